@@ -0,0 +1,126 @@
+//! Boot information the UEFI loader hands the kernel
+//!
+//! The kernel includes this file by path (kernel/src/boot/uefi.rs), so both
+//! sides are built from one definition of the layout. Pointers are virtual
+//! addresses in the direct map at `phys_offset`; addresses described as
+//! physical are not.
+
+/// "RUSTOSBI"
+pub const MAGIC: u64 = 0x4942_534f_5453_5552;
+pub const VERSION: u32 = 1;
+
+pub const MODULE_NAME_LEN: usize = 64;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    /// Page tables, this structure and the loader itself
+    Loader,
+    /// Kernel segments, its stack and the modules
+    Kernel,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    /// Physical, page-aligned
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureBoot {
+    /// Firmware Secure Boot is off and the kernel carried no valid
+    /// signature
+    Off,
+    /// Firmware Secure Boot is off, but the kernel's signature checked out
+    /// against an enrolled key
+    Signed,
+    /// Firmware Secure Boot is on and the kernel's signature checked out;
+    /// the loader refuses to boot anything else
+    Enforced,
+}
+
+/// A linear framebuffer from GOP, its pixel layout as channel masks.
+/// `address` is zero when there is none.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    /// Physical
+    pub address: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per scan line
+    pub stride: u32,
+    pub bpp: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    /// Physical
+    pub start: u64,
+    pub size: u64,
+    /// NUL-padded
+    pub name: [u8; MODULE_NAME_LEN],
+}
+
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    pub secure_boot: SecureBoot,
+    /// Where all of physical memory is mapped
+    pub phys_offset: u64,
+    /// How much of it, from physical address 0
+    pub direct_map_size: u64,
+    pub memory_map: *const MemoryRegion,
+    pub memory_map_len: u64,
+    pub framebuffer: Framebuffer,
+    /// Physical address of the ACPI RSDP, or zero
+    pub rsdp: u64,
+    pub cmdline: *const u8,
+    pub cmdline_len: u64,
+    pub modules: *const Module,
+    pub module_count: u64,
+}
+
+// Empty lists may come with null pointers
+unsafe fn slice<'a, T>(ptr: *const T, len: u64) -> &'a [T] {
+    if ptr.is_null() || len == 0 {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(ptr, len as usize) }
+}
+
+impl BootInfo {
+    pub fn memory_map(&self) -> &[MemoryRegion] {
+        unsafe { slice(self.memory_map, self.memory_map_len) }
+    }
+
+    pub fn cmdline(&self) -> &[u8] {
+        unsafe { slice(self.cmdline, self.cmdline_len) }
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        unsafe { slice(self.modules, self.module_count) }
+    }
+}
+
+impl Module {
+    pub fn name(&self) -> &[u8] {
+        let len = self.name.iter().position(|&byte| byte == 0).unwrap_or(MODULE_NAME_LEN);
+        &self.name[..len]
+    }
+}
@@ -3,8 +3,34 @@
 
 use core::panic::PanicInfo;
 
+// The accessors are for the kernel's side
+#[cfg(target_os = "uefi")]
+#[allow(dead_code)]
+mod handoff;
+// The BIOS and UEFI builds each use part of it
+#[allow(dead_code)]
 mod secure_boot;
+#[cfg(target_os = "uefi")]
+mod uefi;
 
+// Ed25519 and SHA-512 for kernel signatures, shared with the kernel
+#[cfg(target_os = "uefi")]
+extern crate alloc;
+#[cfg(target_os = "uefi")]
+#[allow(unused)]
+#[path = "../../kernel/src/crypto/mod.rs"]
+mod crypto;
+
+// The kernel's crypto code logs through this
+#[cfg(target_os = "uefi")]
+#[macro_export]
+macro_rules! serial_println {
+    ($($arg:tt)*) => {
+        $crate::uefi::print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[cfg(not(target_os = "uefi"))]
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     // Early security initialization
@@ -25,6 +51,10 @@ pub extern "C" fn _start() -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // On the firmware console while boot services are up
+    #[cfg(target_os = "uefi")]
+    uefi::print(format_args!("Loader panic: {}\n", info));
+
     // Output error to serial port if available
     if let Some(location) = info.location() {
         // Would output to serial: file, line, column
@@ -36,4 +66,4 @@ fn panic(info: &PanicInfo) -> ! {
             core::arch::asm!("hlt");
         }
     }
-}
\ No newline at end of file
+}
@@ -3,6 +3,101 @@ use core::arch::asm;
 const KERNEL_START: u64 = 0x100000;  // 1MB
 const KERNEL_SIZE: u64 = 0x200000;   // 2MB max kernel size
 
+// Kernel signing keys, Ed25519 public keys
+pub const KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+const MAX_KEYS: usize = 16;
+
+// Built in at compile time: comma-separated hex public keys
+const BUILTIN_KEYS: Option<&str> = option_env!("KERNEL_SIGNING_KEYS");
+
+/// The kernel signing keys the loader trusts: those built in and those
+/// enrolled in firmware
+pub struct KeyRing {
+    keys: [[u8; KEY_SIZE]; MAX_KEYS],
+    count: usize,
+}
+
+impl KeyRing {
+    pub const fn new() -> Self {
+        KeyRing { keys: [[0; KEY_SIZE]; MAX_KEYS], count: 0 }
+    }
+
+    pub fn with_builtin_keys() -> Self {
+        let mut ring = Self::new();
+        for hex in BUILTIN_KEYS.unwrap_or("").split(',').filter(|hex| !hex.trim().is_empty()) {
+            match parse_key(hex.trim()) {
+                Some(key) => {
+                    ring.enroll(key);
+                }
+                None => panic!("KERNEL_SIGNING_KEYS: not a 32-byte hex key: {}", hex),
+            }
+        }
+        ring
+    }
+
+    /// False when the ring is full
+    pub fn enroll(&mut self, key: [u8; KEY_SIZE]) -> bool {
+        if self.keys().contains(&key) {
+            return true;
+        }
+        match self.keys.get_mut(self.count) {
+            Some(slot) => {
+                *slot = key;
+                self.count += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn keys(&self) -> &[[u8; KEY_SIZE]] {
+        &self.keys[..self.count]
+    }
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    if hex.len() != KEY_SIZE * 2 {
+        return None;
+    }
+    let mut key = [0; KEY_SIZE];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Check a kernel image's detached Ed25519 signature against the enrolled
+/// keys. With firmware Secure Boot on, the chain must not break here, so
+/// a missing or bad signature is an error; otherwise it is only reported.
+#[cfg(target_os = "uefi")]
+pub fn verify_kernel_image(
+    image: &[u8],
+    signature: Option<&[u8]>,
+    keys: &KeyRing,
+    firmware_secure_boot: bool,
+) -> Result<crate::handoff::SecureBoot, &'static str> {
+    use crate::crypto::asymmetric::verify_signature;
+    use crate::crypto::SignatureScheme;
+    use crate::handoff::SecureBoot;
+
+    let verified = match signature {
+        Some(signature) if signature.len() == SIGNATURE_SIZE => keys
+            .keys()
+            .iter()
+            .any(|key| verify_signature(SignatureScheme::Ed25519, key, image, signature).unwrap_or(false)),
+        _ => false,
+    };
+    match (verified, firmware_secure_boot) {
+        (true, true) => Ok(SecureBoot::Enforced),
+        (true, false) => Ok(SecureBoot::Signed),
+        (false, false) => Ok(SecureBoot::Off),
+        (false, true) if keys.keys().is_empty() => Err("Secure Boot is on but no kernel signing keys are enrolled"),
+        (false, true) if signature.is_none() => Err("Secure Boot is on and the kernel is not signed"),
+        (false, true) => Err("Secure Boot is on and the kernel signature does not match an enrolled key"),
+    }
+}
+
 pub fn early_init() {
     // Enable CPU security features early
     enable_nx_bit();
@@ -12,7 +107,7 @@ pub fn early_init() {
     init_entropy();
 }
 
-fn enable_nx_bit() {
+pub fn enable_nx_bit() {
     // Enable NX/XD bit (No Execute)
     unsafe {
         // Set NXE bit in EFER MSR
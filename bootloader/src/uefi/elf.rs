@@ -0,0 +1,109 @@
+//! Loading the kernel's ELF image
+//!
+//! Only what the kernel's own link produces is handled: a little-endian
+//! x86-64 executable whose loadable segments start on page boundaries.
+
+use alloc::vec::Vec;
+
+use super::ffi::PAGE_SIZE;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// A loaded segment: `pages` pages at `phys`, to be mapped at `virt`
+pub struct Segment {
+    pub virt: u64,
+    pub phys: u64,
+    pub pages: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+pub struct Kernel {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+fn read<const N: usize>(image: &[u8], offset: u64) -> Result<[u8; N], &'static str> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|offset| image.get(offset..offset.checked_add(N)?))
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or("kernel ELF truncated")
+}
+
+fn u16_at(image: &[u8], offset: u64) -> Result<u16, &'static str> {
+    read(image, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(image: &[u8], offset: u64) -> Result<u32, &'static str> {
+    read(image, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(image: &[u8], offset: u64) -> Result<u64, &'static str> {
+    read(image, offset).map(u64::from_le_bytes)
+}
+
+/// Copy the loadable segments of `image` into fresh pages of
+/// `memory_type`, zeroing what the file leaves out
+pub fn load(image: &[u8], memory_type: u32) -> Result<Kernel, &'static str> {
+    let ident: [u8; 6] = read(image, 0)?;
+    if &ident[..4] != ELF_MAGIC || ident[4] != CLASS_64 || ident[5] != DATA_LITTLE_ENDIAN {
+        return Err("kernel is not a 64-bit little-endian ELF file");
+    }
+    if u16_at(image, 16)? != TYPE_EXECUTABLE || u16_at(image, 18)? != MACHINE_X86_64 {
+        return Err("kernel is not an x86-64 executable");
+    }
+    let entry = u64_at(image, 24)?;
+    let program_headers = u64_at(image, 32)?;
+    let entry_size = u16_at(image, 54)? as u64;
+    let count = u16_at(image, 56)? as u64;
+    if entry_size < PROGRAM_HEADER_SIZE as u64 {
+        return Err("kernel ELF program headers too small");
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for index in 0..count {
+        let header = program_headers + index * entry_size;
+        if u32_at(image, header)? != PT_LOAD {
+            continue;
+        }
+        let flags = u32_at(image, header + 4)?;
+        let offset = u64_at(image, header + 8)?;
+        let virt = u64_at(image, header + 16)?;
+        let file_size = u64_at(image, header + 32)?;
+        let memory_size = u64_at(image, header + 40)?;
+        if file_size > memory_size {
+            return Err("kernel ELF segment larger in the file than in memory");
+        }
+        let contents = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| image.get(offset..offset.checked_add(file_size as usize)?))
+            .ok_or("kernel ELF truncated")?;
+
+        let page = virt & !(PAGE_SIZE - 1);
+        let end = virt.checked_add(memory_size).ok_or("kernel ELF segment wraps")?;
+        if segments.iter().any(|segment| page < segment.virt + segment.pages * PAGE_SIZE && segment.virt < end) {
+            return Err("kernel ELF segments share a page");
+        }
+        let pages = (end - page).div_ceil(PAGE_SIZE);
+        let phys = super::allocate_pages(pages, memory_type)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(contents.as_ptr(), (phys + (virt - page)) as *mut u8, contents.len());
+        }
+        segments.push(Segment { virt: page, phys, pages, writable: flags & PF_W != 0, executable: flags & PF_X != 0 });
+    }
+    if segments.is_empty() {
+        return Err("kernel ELF has nothing to load");
+    }
+    Ok(Kernel { entry, segments })
+}
@@ -0,0 +1,241 @@
+//! The parts of the UEFI specification the loader calls into
+//!
+//! Table layouts follow UEFI 2.10; entries the loader never calls are kept
+//! as plain pointers so the ones after them stay at the right offsets.
+
+#![allow(dead_code)]
+
+use core::ffi::c_void;
+
+pub type Handle = *mut c_void;
+pub type Status = usize;
+
+const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+pub const SUCCESS: Status = 0;
+pub const LOAD_ERROR: Status = ERROR_BIT | 1;
+pub const BUFFER_TOO_SMALL: Status = ERROR_BIT | 5;
+pub const NOT_FOUND: Status = ERROR_BIT | 14;
+
+pub fn is_error(status: Status) -> bool {
+    status & ERROR_BIT != 0
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+pub const LOADED_IMAGE_PROTOCOL: Guid =
+    Guid(0x5b1b31a1, 0x9562, 0x11d2, [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]);
+pub const SIMPLE_FILE_SYSTEM_PROTOCOL: Guid =
+    Guid(0x964e5b22, 0x6459, 0x11d2, [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]);
+pub const GRAPHICS_OUTPUT_PROTOCOL: Guid =
+    Guid(0x9042a9de, 0x23dc, 0x4a38, [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a]);
+pub const ACPI_20_TABLE: Guid = Guid(0x8868e871, 0xe4f1, 0x11d3, [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81]);
+pub const ACPI_TABLE: Guid = Guid(0xeb9d2d30, 0x2d88, 0x11d3, [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d]);
+/// Owner of SecureBoot, SetupMode, PK, KEK
+pub const GLOBAL_VARIABLE: Guid = Guid(0x8be4df61, 0x93ca, 0x11d2, [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c]);
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut c_void,
+    pub console_out_handle: Handle,
+    pub con_out: *mut SimpleTextOutput,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut SimpleTextOutput,
+    pub runtime_services: *mut RuntimeServices,
+    pub boot_services: *mut BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *const ConfigurationTable,
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *mut c_void,
+}
+
+#[repr(C)]
+pub struct SimpleTextOutput {
+    pub reset: unsafe extern "efiapi" fn(*mut SimpleTextOutput, bool) -> Status,
+    pub output_string: unsafe extern "efiapi" fn(*mut SimpleTextOutput, *const u16) -> Status,
+}
+
+// EFI_ALLOCATE_TYPE
+pub const ALLOCATE_ANY_PAGES: u32 = 0;
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+
+// EFI_MEMORY_TYPE
+pub const RESERVED_MEMORY_TYPE: u32 = 0;
+pub const LOADER_CODE: u32 = 1;
+pub const LOADER_DATA: u32 = 2;
+pub const BOOT_SERVICES_CODE: u32 = 3;
+pub const BOOT_SERVICES_DATA: u32 = 4;
+pub const RUNTIME_SERVICES_CODE: u32 = 5;
+pub const RUNTIME_SERVICES_DATA: u32 = 6;
+pub const CONVENTIONAL_MEMORY: u32 = 7;
+pub const UNUSABLE_MEMORY: u32 = 8;
+pub const ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const ACPI_MEMORY_NVS: u32 = 10;
+pub const PERSISTENT_MEMORY: u32 = 14;
+/// The first of the types set aside for operating system loaders
+pub const OS_MEMORY_TYPE: u32 = 0x8000_0000;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDescriptor {
+    pub kind: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+    pub raise_tpl: *const c_void,
+    pub restore_tpl: *const c_void,
+    pub allocate_pages: unsafe extern "efiapi" fn(u32, u32, usize, *mut u64) -> Status,
+    pub free_pages: unsafe extern "efiapi" fn(u64, usize) -> Status,
+    pub get_memory_map:
+        unsafe extern "efiapi" fn(*mut usize, *mut MemoryDescriptor, *mut usize, *mut usize, *mut u32) -> Status,
+    pub allocate_pool: unsafe extern "efiapi" fn(u32, usize, *mut *mut u8) -> Status,
+    pub free_pool: unsafe extern "efiapi" fn(*mut u8) -> Status,
+    pub create_event: *const c_void,
+    pub set_timer: *const c_void,
+    pub wait_for_event: *const c_void,
+    pub signal_event: *const c_void,
+    pub close_event: *const c_void,
+    pub check_event: *const c_void,
+    pub install_protocol_interface: *const c_void,
+    pub reinstall_protocol_interface: *const c_void,
+    pub uninstall_protocol_interface: *const c_void,
+    pub handle_protocol: unsafe extern "efiapi" fn(Handle, *const Guid, *mut *mut c_void) -> Status,
+    pub reserved: *const c_void,
+    pub register_protocol_notify: *const c_void,
+    pub locate_handle: *const c_void,
+    pub locate_device_path: *const c_void,
+    pub install_configuration_table: *const c_void,
+    pub load_image: *const c_void,
+    pub start_image: *const c_void,
+    pub exit: *const c_void,
+    pub unload_image: *const c_void,
+    pub exit_boot_services: unsafe extern "efiapi" fn(Handle, usize) -> Status,
+    pub get_next_monotonic_count: *const c_void,
+    pub stall: *const c_void,
+    pub set_watchdog_timer: unsafe extern "efiapi" fn(usize, u64, usize, *const u16) -> Status,
+    pub connect_controller: *const c_void,
+    pub disconnect_controller: *const c_void,
+    pub open_protocol: *const c_void,
+    pub close_protocol: *const c_void,
+    pub open_protocol_information: *const c_void,
+    pub protocols_per_handle: *const c_void,
+    pub locate_handle_buffer: *const c_void,
+    pub locate_protocol: unsafe extern "efiapi" fn(*const Guid, *mut c_void, *mut *mut c_void) -> Status,
+}
+
+// Variable attributes
+pub const VARIABLE_NON_VOLATILE: u32 = 0x1;
+pub const VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x2;
+pub const VARIABLE_RUNTIME_ACCESS: u32 = 0x4;
+
+#[repr(C)]
+pub struct RuntimeServices {
+    pub hdr: TableHeader,
+    pub get_time: *const c_void,
+    pub set_time: *const c_void,
+    pub get_wakeup_time: *const c_void,
+    pub set_wakeup_time: *const c_void,
+    pub set_virtual_address_map: *const c_void,
+    pub convert_pointer: *const c_void,
+    pub get_variable: unsafe extern "efiapi" fn(*const u16, *const Guid, *mut u32, *mut usize, *mut c_void) -> Status,
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *mut SystemTable,
+    pub device_handle: Handle,
+    pub file_path: *const c_void,
+    pub reserved: *const c_void,
+    pub load_options_size: u32,
+    pub load_options: *const c_void,
+    pub image_base: *const c_void,
+    pub image_size: u64,
+    pub image_code_type: u32,
+    pub image_data_type: u32,
+    pub unload: *const c_void,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystem {
+    pub revision: u64,
+    pub open_volume: unsafe extern "efiapi" fn(*mut SimpleFileSystem, *mut *mut File) -> Status,
+}
+
+pub const FILE_MODE_READ: u64 = 0x1;
+
+#[repr(C)]
+pub struct File {
+    pub revision: u64,
+    pub open: unsafe extern "efiapi" fn(*mut File, *mut *mut File, *const u16, u64, u64) -> Status,
+    pub close: unsafe extern "efiapi" fn(*mut File) -> Status,
+    pub delete: *const c_void,
+    pub read: unsafe extern "efiapi" fn(*mut File, *mut usize, *mut c_void) -> Status,
+    pub write: *const c_void,
+    pub get_position: unsafe extern "efiapi" fn(*mut File, *mut u64) -> Status,
+    pub set_position: unsafe extern "efiapi" fn(*mut File, u64) -> Status,
+}
+
+// EFI_GRAPHICS_PIXEL_FORMAT
+pub const PIXEL_RGB_RESERVED_8BIT: u32 = 0;
+pub const PIXEL_BGR_RESERVED_8BIT: u32 = 1;
+pub const PIXEL_BIT_MASK: u32 = 2;
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    pub query_mode: *const c_void,
+    pub set_mode: *const c_void,
+    pub blt: *const c_void,
+    pub mode: *const GraphicsOutputMode,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsOutputModeInfo,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+#[repr(C)]
+pub struct GraphicsOutputModeInfo {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: u32,
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub reserved_mask: u32,
+    pub pixels_per_scan_line: u32,
+}
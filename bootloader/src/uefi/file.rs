@@ -0,0 +1,78 @@
+//! Reading files from the partition the loader was started from
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr;
+
+use super::ffi::{self, File, Handle, LoadedImage, SimpleFileSystem};
+use super::{boot_services, check, ucs2};
+
+/// The root directory of a FAT volume
+pub struct Volume {
+    root: *mut File,
+}
+
+impl Volume {
+    /// The volume the loader's own image was read from
+    pub fn boot_volume(image: Handle) -> Result<Self, &'static str> {
+        let services = boot_services().ok_or("boot services exited")?;
+        let mut loaded_image: *mut c_void = ptr::null_mut();
+        let status = unsafe { (services.handle_protocol)(image, &ffi::LOADED_IMAGE_PROTOCOL, &mut loaded_image) };
+        check(status, "loaded image protocol")?;
+        let device = unsafe { (*(loaded_image as *const LoadedImage)).device_handle };
+
+        let mut file_system: *mut c_void = ptr::null_mut();
+        let status = unsafe { (services.handle_protocol)(device, &ffi::SIMPLE_FILE_SYSTEM_PROTOCOL, &mut file_system) };
+        check(status, "file system protocol on the boot device")?;
+        let file_system = file_system as *mut SimpleFileSystem;
+
+        let mut root = ptr::null_mut();
+        check(unsafe { ((*file_system).open_volume)(file_system, &mut root) }, "opening the boot volume")?;
+        Ok(Volume { root })
+    }
+
+    /// The whole of the file at `path`, or None if there is none
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>, &'static str> {
+        let name = ucs2(path);
+        let mut file: *mut File = ptr::null_mut();
+        let status = unsafe { ((*self.root).open)(self.root, &mut file, name.as_ptr(), ffi::FILE_MODE_READ, 0) };
+        if status == ffi::NOT_FOUND {
+            return Ok(None);
+        }
+        check(status, "opening a file")?;
+
+        let contents = unsafe { read_file(file) };
+        unsafe { ((*file).close)(file) };
+        contents.map(Some)
+    }
+}
+
+// Seeking to u64::MAX moves to the end of a file, which gives its size
+unsafe fn read_file(file: *mut File) -> Result<Vec<u8>, &'static str> {
+    let mut size = 0;
+    unsafe {
+        check(((*file).set_position)(file, u64::MAX), "seeking in a file")?;
+        check(((*file).get_position)(file, &mut size), "seeking in a file")?;
+        check(((*file).set_position)(file, 0), "seeking in a file")?;
+    }
+    let mut contents = alloc::vec![0u8; size as usize];
+    let mut done = 0;
+    while done < contents.len() {
+        let mut chunk = contents.len() - done;
+        let status = unsafe { ((*file).read)(file, &mut chunk, contents[done..].as_mut_ptr().cast()) };
+        check(status, "reading a file")?;
+        if chunk == 0 {
+            return Err("file shorter than its size");
+        }
+        done += chunk;
+    }
+    Ok(contents)
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        if boot_services().is_some() {
+            unsafe { ((*self.root).close)(self.root) };
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! UEFI loader
+//!
+//! Built for x86_64-unknown-uefi, the loader is an EFI application the
+//! firmware starts from the EFI system partition. While boot services are
+//! up it reads the kernel, its detached signature, the command line and an
+//! optional initrd from `\EFI\rust_os\` on the same partition, checks the
+//! signature against the enrolled keys (secure_boot), loads the kernel's
+//! segments, builds page tables and collects the GOP framebuffer and the
+//! ACPI RSDP. It then takes the final memory map, exits boot services and
+//! jumps to the kernel with a `handoff::BootInfo`.
+//!
+//! The kernel gets the same address space the other loaders give it:
+//! physical memory mapped at `PHYS_OFFSET`, itself at its link address in
+//! the top 2 GiB, and the first 4 GiB identity-mapped.
+
+use alloc::vec::Vec;
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::ffi::c_void;
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::handoff::{self, BootInfo, MemoryRegion, Module, RegionKind};
+use crate::secure_boot::{self, KeyRing, KEY_SIZE};
+
+macro_rules! println {
+    ($($arg:tt)*) => {
+        $crate::uefi::print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+mod elf;
+mod ffi;
+mod file;
+mod paging;
+
+use ffi::{BootServices, Guid, Handle, Status, SystemTable};
+
+/// Where the kernel expects all of physical memory
+pub const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+const KERNEL_PATH: &str = "\\EFI\\rust_os\\kernel.elf";
+const SIGNATURE_PATH: &str = "\\EFI\\rust_os\\kernel.sig";
+const CMDLINE_PATH: &str = "\\EFI\\rust_os\\cmdline.txt";
+const INITRD_PATH: &str = "\\EFI\\rust_os\\initrd.img";
+
+const KERNEL_STACK_SIZE: u64 = 512 * 1024;
+
+// Memory the kernel keeps: its segments, stack and the initrd
+const KERNEL_MEMORY: u32 = ffi::OS_MEMORY_TYPE;
+// Memory the loader hands over: page tables and the boot information
+const BOOT_INFO_MEMORY: u32 = ffi::OS_MEMORY_TYPE + 1;
+
+// Kernel signing keys enrolled in firmware, concatenated 32-byte Ed25519
+// public keys
+const KEYS_VARIABLE: &str = "RustOsKernelKeys";
+const KEYS_VARIABLE_GUID: Guid = Guid(0x6d2f_1c4a, 0x93b7, 0x4e0f, [0x8a, 0x51, 0x2c, 0x7e, 0x90, 0x1b, 0xd4, 0x36]);
+
+// Room for entries the allocations after the first memory map add
+const MEMORY_MAP_SLACK: usize = 64;
+
+// Null once boot services have been exited
+static SYSTEM_TABLE: AtomicPtr<SystemTable> = AtomicPtr::new(ptr::null_mut());
+
+fn system_table() -> Option<&'static SystemTable> {
+    unsafe { SYSTEM_TABLE.load(Ordering::Acquire).as_ref() }
+}
+
+fn boot_services() -> Option<&'static BootServices> {
+    system_table().and_then(|table| unsafe { table.boot_services.as_ref() })
+}
+
+fn check(status: Status, what: &'static str) -> Result<(), &'static str> {
+    if ffi::is_error(status) {
+        println!("{}: status {:#x}", what, status);
+        return Err(what);
+    }
+    Ok(())
+}
+
+/// NUL-terminated UCS-2
+fn ucs2(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain([0]).collect()
+}
+
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let Some(out) = system_table().and_then(|table| unsafe { table.con_out.as_mut() }) else {
+            return Ok(());
+        };
+        let mut buffer = [0u16; 128];
+        let mut len = 0;
+        for unit in text.encode_utf16() {
+            if unit == u16::from(b'\n') {
+                buffer[len] = u16::from(b'\r');
+                len += 1;
+            }
+            buffer[len] = unit;
+            len += 1;
+            if len >= buffer.len() - 2 {
+                buffer[len] = 0;
+                unsafe { (out.output_string)(out, buffer.as_ptr()) };
+                len = 0;
+            }
+        }
+        buffer[len] = 0;
+        unsafe { (out.output_string)(out, buffer.as_ptr()) };
+        Ok(())
+    }
+}
+
+/// Print on the firmware console, while boot services are up
+pub fn print(args: fmt::Arguments) {
+    let _ = Console.write_fmt(args);
+}
+
+// Pool allocations only guarantee 8-byte alignment: over-allocate and keep
+// the pool pointer just below the aligned block
+struct PoolAllocator;
+
+unsafe impl GlobalAlloc for PoolAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(services) = boot_services() else {
+            return ptr::null_mut();
+        };
+        let align = layout.align().max(8);
+        let mut pool = ptr::null_mut();
+        let status = unsafe { (services.allocate_pool)(ffi::LOADER_DATA, layout.size() + align + 8, &mut pool) };
+        if ffi::is_error(status) {
+            return ptr::null_mut();
+        }
+        let block = (pool as usize + 8 + align - 1) & !(align - 1);
+        unsafe { (block as *mut *mut u8).sub(1).write(pool) };
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, _layout: Layout) {
+        // Left in place once the firmware has let go of memory
+        if let Some(services) = boot_services() {
+            unsafe { (services.free_pool)((block as *mut *mut u8).sub(1).read()) };
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PoolAllocator = PoolAllocator;
+
+/// Zeroed pages of `memory_type`, by physical address; the firmware
+/// identity-maps memory
+fn allocate_pages(pages: u64, memory_type: u32) -> Result<u64, &'static str> {
+    let services = boot_services().ok_or("boot services exited")?;
+    let mut address = 0;
+    let status = unsafe { (services.allocate_pages)(ffi::ALLOCATE_ANY_PAGES, memory_type, pages as usize, &mut address) };
+    check(status, "AllocatePages")?;
+    unsafe { ptr::write_bytes(address as *mut u8, 0, (pages * ffi::PAGE_SIZE) as usize) };
+    Ok(address)
+}
+
+fn pages_for(bytes: u64) -> u64 {
+    bytes.div_ceil(ffi::PAGE_SIZE)
+}
+
+/// A firmware variable's attributes and contents
+fn variable(name: &str, guid: &Guid) -> Option<(u32, Vec<u8>)> {
+    let runtime = system_table().and_then(|table| unsafe { table.runtime_services.as_ref() })?;
+    let name = ucs2(name);
+    let mut attributes = 0;
+    let mut size = 0;
+    let status = unsafe { (runtime.get_variable)(name.as_ptr(), guid, &mut attributes, &mut size, ptr::null_mut()) };
+    if status != ffi::BUFFER_TOO_SMALL {
+        return None;
+    }
+    let mut data = alloc::vec![0u8; size];
+    let status =
+        unsafe { (runtime.get_variable)(name.as_ptr(), guid, &mut attributes, &mut size, data.as_mut_ptr().cast()) };
+    if ffi::is_error(status) {
+        return None;
+    }
+    data.truncate(size);
+    Some((attributes, data))
+}
+
+fn firmware_secure_boot() -> bool {
+    matches!(variable("SecureBoot", &ffi::GLOBAL_VARIABLE), Some((_, value)) if value.first() == Some(&1))
+}
+
+/// The built-in keys and those enrolled in the firmware variable. Only a
+/// variable without runtime access counts: the OS could have written one
+/// with it.
+fn enrolled_keys() -> KeyRing {
+    let mut keys = KeyRing::with_builtin_keys();
+    if let Some((attributes, data)) = variable(KEYS_VARIABLE, &KEYS_VARIABLE_GUID) {
+        if attributes & ffi::VARIABLE_RUNTIME_ACCESS != 0 {
+            println!("Ignoring {}: it is writable at runtime", KEYS_VARIABLE);
+        } else {
+            for key in data.as_chunks::<KEY_SIZE>().0 {
+                if !keys.enroll(*key) {
+                    println!("Too many kernel signing keys; ignoring the rest");
+                    break;
+                }
+            }
+        }
+    }
+    keys
+}
+
+fn framebuffer() -> handoff::Framebuffer {
+    let mut framebuffer =
+        handoff::Framebuffer { address: 0, size: 0, width: 0, height: 0, stride: 0, bpp: 0, red_mask: 0, green_mask: 0, blue_mask: 0 };
+    let Some(services) = boot_services() else {
+        return framebuffer;
+    };
+    let mut gop: *mut c_void = ptr::null_mut();
+    let status = unsafe { (services.locate_protocol)(&ffi::GRAPHICS_OUTPUT_PROTOCOL, ptr::null_mut(), &mut gop) };
+    if ffi::is_error(status) {
+        println!("No graphics output");
+        return framebuffer;
+    }
+    let gop = unsafe { &*(gop as *const ffi::GraphicsOutput) };
+    let mode = unsafe { &*gop.mode };
+    let info = unsafe { &*mode.info };
+    let (red, green, blue) = match info.pixel_format {
+        ffi::PIXEL_RGB_RESERVED_8BIT => (0xff, 0xff00, 0xff_0000),
+        ffi::PIXEL_BGR_RESERVED_8BIT => (0xff_0000, 0xff00, 0xff),
+        ffi::PIXEL_BIT_MASK => (info.red_mask, info.green_mask, info.blue_mask),
+        // Blt only: no linear framebuffer
+        _ => return framebuffer,
+    };
+    let used = red | green | blue | info.reserved_mask;
+    framebuffer = handoff::Framebuffer {
+        address: mode.frame_buffer_base,
+        size: mode.frame_buffer_size as u64,
+        width: info.horizontal_resolution,
+        height: info.vertical_resolution,
+        stride: info.pixels_per_scan_line,
+        bpp: u32::BITS - used.leading_zeros(),
+        red_mask: red,
+        green_mask: green,
+        blue_mask: blue,
+    };
+    framebuffer
+}
+
+fn rsdp() -> u64 {
+    let Some(table) = system_table() else {
+        return 0;
+    };
+    let tables = unsafe { core::slice::from_raw_parts(table.configuration_table, table.number_of_table_entries) };
+    let find = |guid| tables.iter().find(|entry| entry.vendor_guid == guid).map(|entry| entry.vendor_table as u64);
+    find(ffi::ACPI_20_TABLE).or_else(|| find(ffi::ACPI_TABLE)).unwrap_or(0)
+}
+
+// The memory map's size and the end of the highest range of RAM
+fn memory_map_extent() -> Result<(usize, u64), &'static str> {
+    let services = boot_services().ok_or("boot services exited")?;
+    let (mut size, mut key, mut descriptor_size, mut version) = (0, 0, 0, 0);
+    let status =
+        unsafe { (services.get_memory_map)(&mut size, ptr::null_mut(), &mut key, &mut descriptor_size, &mut version) };
+    if status != ffi::BUFFER_TOO_SMALL {
+        check(status, "GetMemoryMap")?;
+    }
+    let mut buffer = alloc::vec![0u8; size + 8 * descriptor_size];
+    size = buffer.len();
+    let status = unsafe {
+        (services.get_memory_map)(&mut size, buffer.as_mut_ptr().cast(), &mut key, &mut descriptor_size, &mut version)
+    };
+    check(status, "GetMemoryMap")?;
+    let end = buffer[..size]
+        .chunks_exact(descriptor_size)
+        .map(|descriptor| unsafe { descriptor.as_ptr().cast::<ffi::MemoryDescriptor>().read_unaligned() })
+        .filter(|descriptor| descriptor.kind != ffi::RESERVED_MEMORY_TYPE)
+        .map(|descriptor| descriptor.physical_start + descriptor.number_of_pages * ffi::PAGE_SIZE)
+        .max()
+        .unwrap_or(0);
+    Ok((size, end))
+}
+
+fn region_kind(memory_type: u32) -> RegionKind {
+    match memory_type {
+        // The loader is done with its own image and pool once the kernel
+        // runs
+        ffi::CONVENTIONAL_MEMORY
+        | ffi::BOOT_SERVICES_CODE
+        | ffi::BOOT_SERVICES_DATA
+        | ffi::LOADER_CODE
+        | ffi::LOADER_DATA => RegionKind::Usable,
+        ffi::ACPI_RECLAIM_MEMORY => RegionKind::AcpiReclaimable,
+        ffi::ACPI_MEMORY_NVS => RegionKind::AcpiNvs,
+        ffi::UNUSABLE_MEMORY => RegionKind::BadMemory,
+        KERNEL_MEMORY => RegionKind::Kernel,
+        BOOT_INFO_MEMORY => RegionKind::Loader,
+        // Runtime services, MMIO, persistent memory and anything newer
+        _ => RegionKind::Reserved,
+    }
+}
+
+/// Exit boot services and turn the final memory map into `regions`,
+/// merging neighbours of one kind. Nothing may allocate from here on.
+fn exit_boot_services(image: Handle, raw: &mut [u8], regions: &mut [MemoryRegion]) -> Result<usize, &'static str> {
+    let services = boot_services().ok_or("boot services exited")?;
+    let mut size;
+    let mut descriptor_size = 0;
+    // The first attempt can fail when an event changes the map in between;
+    // only these two calls may be repeated
+    let mut attempts = 0;
+    loop {
+        let (mut key, mut version) = (0, 0);
+        size = raw.len();
+        let status = unsafe {
+            (services.get_memory_map)(&mut size, raw.as_mut_ptr().cast(), &mut key, &mut descriptor_size, &mut version)
+        };
+        if ffi::is_error(status) {
+            return Err("GetMemoryMap before ExitBootServices");
+        }
+        if unsafe { (services.exit_boot_services)(image, key) } == ffi::SUCCESS {
+            break;
+        }
+        attempts += 1;
+        if attempts == 2 {
+            return Err("ExitBootServices");
+        }
+    }
+    SYSTEM_TABLE.store(ptr::null_mut(), Ordering::Release);
+
+    let mut count = 0usize;
+    for descriptor in raw[..size].chunks_exact(descriptor_size) {
+        let descriptor = unsafe { descriptor.as_ptr().cast::<ffi::MemoryDescriptor>().read_unaligned() };
+        let (start, kind) = (descriptor.physical_start, region_kind(descriptor.kind));
+        let end = start + descriptor.number_of_pages * ffi::PAGE_SIZE;
+        if count > 0 && regions[count - 1].end == start && regions[count - 1].kind == kind {
+            regions[count - 1].end = end;
+        } else if count < regions.len() {
+            regions[count] = MemoryRegion { start, end, kind };
+            count += 1;
+        } else {
+            // Only the slack ran out; the rest of memory goes unused
+            break;
+        }
+    }
+    Ok(count)
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe fn enter_kernel(pml4: u64, entry: u64, stack_top: u64, info: u64) -> ! {
+    unsafe {
+        asm!(
+            "cli",
+            "mov cr3, {pml4}",
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            // A null return address, as if called
+            "push rbp",
+            "jmp {entry}",
+            pml4 = in(reg) pml4,
+            stack = in(reg) stack_top,
+            entry = in(reg) entry,
+            in("rdi") info,
+            options(noreturn)
+        )
+    }
+}
+
+fn boot(image: Handle) -> Result<(), &'static str> {
+    let services = boot_services().ok_or("no boot services")?;
+    // The firmware resets the machine after five minutes in a loader
+    unsafe { (services.set_watchdog_timer)(0, 0, 0, ptr::null()) };
+    println!("rust_bootloader: UEFI");
+
+    let volume = file::Volume::boot_volume(image)?;
+    let kernel = volume.read(KERNEL_PATH)?.ok_or("\\EFI\\rust_os\\kernel.elf not found")?;
+    let signature = volume.read(SIGNATURE_PATH)?;
+    let cmdline = volume.read(CMDLINE_PATH)?.unwrap_or_default();
+    let initrd = volume.read(INITRD_PATH)?;
+
+    let firmware_secure_boot = firmware_secure_boot();
+    let keys = enrolled_keys();
+    println!(
+        "Secure Boot {}, {} kernel signing keys enrolled",
+        if firmware_secure_boot { "on" } else { "off" },
+        keys.keys().len()
+    );
+    let secure_boot = secure_boot::verify_kernel_image(&kernel, signature.as_deref(), &keys, firmware_secure_boot)?;
+    println!("Kernel: {} bytes, {:?}", kernel.len(), secure_boot);
+
+    let loaded = elf::load(&kernel, KERNEL_MEMORY)?;
+    let stack = allocate_pages(pages_for(KERNEL_STACK_SIZE), KERNEL_MEMORY)?;
+
+    let mut modules = Vec::new();
+    if let Some(initrd) = initrd {
+        let start = allocate_pages(pages_for(initrd.len() as u64).max(1), KERNEL_MEMORY)?;
+        unsafe { ptr::copy_nonoverlapping(initrd.as_ptr(), start as *mut u8, initrd.len()) };
+        let mut name = [0; handoff::MODULE_NAME_LEN];
+        name[..6].copy_from_slice(b"initrd");
+        modules.push(Module { start, size: initrd.len() as u64, name });
+    }
+    let cmdline = match cmdline.iter().position(|&byte| byte == b'\n' || byte == b'\r' || byte == 0) {
+        Some(end) => &cmdline[..end],
+        None => &cmdline[..],
+    };
+
+    let framebuffer = framebuffer();
+    let rsdp = rsdp();
+
+    let (map_size, memory_end) = memory_map_extent()?;
+    let direct_map_size = memory_end.clamp(paging::IDENTITY_MAP_SIZE, paging::MAX_DIRECT_MAP_SIZE);
+    let mut tables = paging::PageTables::new(BOOT_INFO_MEMORY)?;
+    tables.map_physical(direct_map_size, PHYS_OFFSET)?;
+    for segment in &loaded.segments {
+        tables.map_segment(segment)?;
+    }
+
+    // Boot information, memory map, modules and command line in one
+    // allocation
+    let capacity = map_size / size_of::<ffi::MemoryDescriptor>() + MEMORY_MAP_SLACK;
+    let regions_offset = round_up(size_of::<BootInfo>(), 8);
+    let modules_offset = round_up(regions_offset + capacity * size_of::<MemoryRegion>(), 8);
+    let cmdline_offset = modules_offset + modules.len() * size_of::<Module>();
+    let info_size = cmdline_offset + cmdline.len();
+    let info_phys = allocate_pages(pages_for(info_size as u64), BOOT_INFO_MEMORY)?;
+    let virt = |offset: usize| PHYS_OFFSET + info_phys + offset as u64;
+    unsafe {
+        ptr::copy_nonoverlapping(modules.as_ptr(), (info_phys as usize + modules_offset) as *mut Module, modules.len());
+        ptr::copy_nonoverlapping(cmdline.as_ptr(), (info_phys as usize + cmdline_offset) as *mut u8, cmdline.len());
+    }
+    let regions =
+        unsafe { core::slice::from_raw_parts_mut((info_phys as usize + regions_offset) as *mut MemoryRegion, capacity) };
+
+    // The memory map buffer is allocated last, so that what it describes
+    // only changes by the buffer itself
+    let (raw_size, _) = memory_map_extent()?;
+    let mut raw = alloc::vec![0u8; raw_size + 8 * size_of::<ffi::MemoryDescriptor>()];
+
+    println!("Starting kernel at {:#x}", loaded.entry);
+    let region_count = exit_boot_services(image, &mut raw, regions)?;
+
+    let info = BootInfo {
+        magic: handoff::MAGIC,
+        version: handoff::VERSION,
+        secure_boot,
+        phys_offset: PHYS_OFFSET,
+        direct_map_size,
+        memory_map: virt(regions_offset) as *const MemoryRegion,
+        memory_map_len: region_count as u64,
+        framebuffer,
+        rsdp,
+        cmdline: virt(cmdline_offset) as *const u8,
+        cmdline_len: cmdline.len() as u64,
+        modules: virt(modules_offset) as *const Module,
+        module_count: modules.len() as u64,
+    };
+    unsafe {
+        (info_phys as *mut BootInfo).write(info);
+        if tables.no_execute() {
+            secure_boot::enable_nx_bit();
+        }
+        enter_kernel(tables.root(), loaded.entry, PHYS_OFFSET + stack + KERNEL_STACK_SIZE, virt(0))
+    }
+}
+
+#[no_mangle]
+extern "efiapi" fn efi_main(image: Handle, system_table: *mut SystemTable) -> Status {
+    SYSTEM_TABLE.store(system_table, Ordering::Release);
+    match boot(image) {
+        Ok(()) => ffi::SUCCESS,
+        Err(error) => {
+            println!("Boot failed: {}", error);
+            ffi::LOAD_ERROR
+        }
+    }
+}
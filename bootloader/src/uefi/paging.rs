@@ -0,0 +1,106 @@
+//! The kernel's initial page tables
+//!
+//! Physical memory is mapped twice with 2 MiB pages sharing one set of
+//! directories: at zero, so the loader keeps running after the switch and
+//! the kernel's fixed low addresses stay valid, and at the direct-map
+//! offset. The kernel's segments get 4 KiB pages with the permissions
+//! their ELF flags ask for.
+
+use core::arch::x86_64::__cpuid;
+
+use super::elf::Segment;
+use super::ffi::PAGE_SIZE;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const ENTRIES: u64 = 512;
+const HUGE_PAGE_SIZE: u64 = 2 << 20;
+const GIB: u64 = 1 << 30;
+
+/// Always mapped, for the devices below 4 GiB
+pub const IDENTITY_MAP_SIZE: u64 = 4 * GIB;
+/// What one directory pointer table covers
+pub const MAX_DIRECT_MAP_SIZE: u64 = ENTRIES * GIB;
+
+pub struct PageTables {
+    root: u64,
+    memory_type: u32,
+    no_execute: bool,
+}
+
+fn table(address: u64) -> &'static mut [u64; ENTRIES as usize] {
+    // Tables are in identity-mapped loader memory until the switch
+    unsafe { &mut *(address as *mut [u64; ENTRIES as usize]) }
+}
+
+fn index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * level)) & (ENTRIES - 1)) as usize
+}
+
+impl PageTables {
+    /// Tables are allocated from pages of `memory_type`
+    pub fn new(memory_type: u32) -> Result<Self, &'static str> {
+        // CPUID 0x80000001 EDX bit 20: no-execute pages
+        let no_execute = __cpuid(0x8000_0001).edx & (1 << 20) != 0;
+        Ok(PageTables { root: super::allocate_pages(1, memory_type)?, memory_type, no_execute })
+    }
+
+    /// Physical address of the top-level table, for CR3
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Whether the tables use the no-execute bit, which EFER.NXE has to
+    /// be on for
+    pub fn no_execute(&self) -> bool {
+        self.no_execute
+    }
+
+    fn next(&self, entry: &mut u64) -> Result<u64, &'static str> {
+        if *entry & PRESENT == 0 {
+            *entry = super::allocate_pages(1, self.memory_type)? | PRESENT | WRITABLE;
+        }
+        if *entry & HUGE_PAGE != 0 {
+            return Err("kernel segment overlaps the direct map");
+        }
+        Ok(*entry & ADDRESS_MASK)
+    }
+
+    /// Map physical memory up to `size` at zero and at `offset`
+    pub fn map_physical(&mut self, size: u64, offset: u64) -> Result<(), &'static str> {
+        let pdpt = super::allocate_pages(1, self.memory_type)?;
+        for gib in 0..size.div_ceil(GIB).min(ENTRIES) {
+            let directory = super::allocate_pages(1, self.memory_type)?;
+            for (slot, entry) in table(directory).iter_mut().enumerate() {
+                *entry = (gib * GIB + slot as u64 * HUGE_PAGE_SIZE) | PRESENT | WRITABLE | HUGE_PAGE;
+            }
+            table(pdpt)[gib as usize] = directory | PRESENT | WRITABLE;
+        }
+        table(self.root)[0] = pdpt | PRESENT | WRITABLE;
+        table(self.root)[index(offset, 3)] = pdpt | PRESENT | WRITABLE;
+        Ok(())
+    }
+
+    pub fn map_segment(&mut self, segment: &Segment) -> Result<(), &'static str> {
+        let mut flags = PRESENT;
+        if segment.writable {
+            flags |= WRITABLE;
+        }
+        if !segment.executable && self.no_execute {
+            flags |= NO_EXECUTE;
+        }
+        for page in 0..segment.pages {
+            let virt = segment.virt + page * PAGE_SIZE;
+            let mut address = self.root;
+            for level in (1..4).rev() {
+                address = self.next(&mut table(address)[index(virt, level)])?;
+            }
+            table(address)[index(virt, 0)] = (segment.phys + page * PAGE_SIZE) | flags;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+// Link with the layout the chosen boot protocol needs; the bootimage
+// loader uses the default one.
+
+use std::env;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=linker");
+    if env::var_os("CARGO_FEATURE_MULTIBOOT2").is_some() {
+        println!("cargo:rustc-link-arg-bins=-T{}/linker/multiboot2.ld", dir);
+    } else if env::var_os("CARGO_FEATURE_LIMINE").is_some() {
+        println!("cargo:rustc-link-arg-bins=-T{}/linker/limine.ld", dir);
+    } else if env::var_os("CARGO_FEATURE_UEFI").is_some() {
+        println!("cargo:rustc-link-arg-bins=-T{}/linker/uefi.ld", dir);
+    }
+}
@@ -1,6 +1,7 @@
 // APIC (Advanced Programmable Interrupt Controller) Support
 use super::tables::{Madt, MadtEntryHeader, MadtEntryType, MadtLocalApic, MadtIoApic, MadtInterruptSourceOverride};
 use super::SdtHeader;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use x86_64::PhysAddr;
 use crate::memory::PHYS_MEM_OFFSET;
@@ -287,6 +288,11 @@ impl IoApic {
         }
     }
     
+    /// Whether global system interrupt `gsi` is one of this I/O APIC's inputs
+    pub fn covers(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.max_entries as u32
+    }
+
     fn irq_to_entry(&self, irq: u8) -> u8 {
         // Map IRQ to I/O APIC entry
         // This would use interrupt overrides from MADT
@@ -298,6 +304,44 @@ lazy_static! {
     static ref APIC_INFO: Mutex<ApicInfo> = Mutex::new(ApicInfo::new());
     static ref LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
     static ref IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+    static ref IRQ_ROUTES: Mutex<BTreeMap<u32, IrqRoute>> = Mutex::new(BTreeMap::new());
+}
+
+/// Where an I/O APIC input is delivered
+#[derive(Debug, Clone, Copy)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub vector: u8,
+    /// Local APIC ID of the CPU that takes it
+    pub dest: u8,
+}
+
+/// Deliver global system interrupt `gsi` as `vector` to the CPU with local
+/// APIC ID `dest`. False if no I/O APIC has that input.
+pub fn route_irq(gsi: u32, vector: u8, dest: u8) -> bool {
+    let io_apics = IO_APICS.lock();
+    let Some(ioapic) = io_apics.iter().find(|ioapic| ioapic.covers(gsi)) else {
+        return false;
+    };
+    ioapic.map_irq((gsi - ioapic.gsi_base) as u8, vector, dest);
+    IRQ_ROUTES.lock().insert(gsi, IrqRoute { gsi, vector, dest });
+    true
+}
+
+/// Send the interrupts routed to local APIC `from` to `to` instead.
+/// Returns how many moved.
+pub fn redirect_irqs(from: u8, to: u8) -> usize {
+    let moved: Vec<IrqRoute> = IRQ_ROUTES
+        .lock()
+        .values()
+        .filter(|route| route.dest == from)
+        .copied()
+        .collect();
+    moved.iter().filter(|route| route_irq(route.gsi, route.vector, to)).count()
+}
+
+pub fn irq_routes() -> Vec<IrqRoute> {
+    IRQ_ROUTES.lock().values().copied().collect()
 }
 
 pub fn parse_madt(table: *const SdtHeader) -> Result<(), &'static str> {
@@ -0,0 +1,260 @@
+// Processor power states (_CST) from the ACPI namespace
+//
+// There is no AML interpreter, so only the encodings firmware commonly
+// uses are understood: `Name (_CST, Package () {...})`, or a `Method (_CST)`
+// that returns such a package literally. Processors are assumed to report
+// the same states, and the first _CST found in the DSDT or an SSDT is used.
+//
+// The package is a count followed by one package per state:
+//   { Buffer (ResourceTemplate () { Register (...) }), Type, Latency, Power }
+
+use alloc::vec::Vec;
+
+use super::{SdtHeader, DSDT_SIGNATURE, FADT_SIGNATURE, SSDT_SIGNATURE};
+use crate::memory::PHYS_MEM_OFFSET;
+
+pub(super) const NAME_OP: u8 = 0x08;
+const BUFFER_OP: u8 = 0x11;
+pub(super) const PACKAGE_OP: u8 = 0x12;
+pub(super) const METHOD_OP: u8 = 0x14;
+const RETURN_OP: u8 = 0xA4;
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ONES_OP: u8 = 0xFF;
+pub(super) const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const QWORD_PREFIX: u8 = 0x0E;
+
+const GENERIC_REGISTER_DESCRIPTOR: u8 = 0x82;
+const SPACE_SYSTEM_IO: u8 = 0x01;
+const SPACE_FIXED_HARDWARE: u8 = 0x7F;
+// Intel's FFH encoding: vendor 1, class 1 is MWAIT with the hint in the
+// address; bit 1 of the access size asks for bus master avoidance
+const FFH_VENDOR_INTEL: u8 = 1;
+const FFH_CLASS_MWAIT: u8 = 1;
+const FFH_BUS_MASTER_AVOIDANCE: u8 = 1 << 1;
+
+/// How a state is entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// C1 with no register: HLT
+    Halt,
+    /// MWAIT with this hint
+    Mwait { hint: u32, bus_master_avoidance: bool },
+    /// Read this I/O port (P_LVLx)
+    IoPort(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CState {
+    /// ACPI C-state type, 1 to 3
+    pub kind: u8,
+    pub latency_us: u32,
+    pub power_mw: u32,
+    pub entry: Entry,
+}
+
+struct Register {
+    space: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+impl Register {
+    fn entry(&self, kind: u8) -> Option<Entry> {
+        match self.space {
+            SPACE_FIXED_HARDWARE if self.bit_width == FFH_VENDOR_INTEL && self.bit_offset == FFH_CLASS_MWAIT => {
+                Some(Entry::Mwait {
+                    hint: self.address as u32,
+                    bus_master_avoidance: self.access_size & FFH_BUS_MASTER_AVOIDANCE != 0,
+                })
+            }
+            SPACE_SYSTEM_IO if kind > 1 && self.address != 0 => Some(Entry::IoPort(self.address as u16)),
+            // C1 is HLT whatever the register says
+            _ if kind == 1 => Some(Entry::Halt),
+            _ => None,
+        }
+    }
+}
+
+pub(super) struct Aml<'a> {
+    pub(super) data: &'a [u8],
+    pub(super) pos: usize,
+}
+
+impl<'a> Aml<'a> {
+    pub(super) fn byte(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    // PkgLength, returned as the offset where the package ends
+    pub(super) fn pkg_end(&mut self) -> Option<usize> {
+        let start = self.pos;
+        let lead = self.byte()?;
+        let extra = (lead >> 6) as usize;
+        let mut length = if extra == 0 { (lead & 0x3F) as usize } else { (lead & 0x0F) as usize };
+        for i in 0..extra {
+            length |= (self.byte()? as usize) << (4 + 8 * i);
+        }
+        let end = start + length;
+        (end <= self.data.len()).then_some(end)
+    }
+
+    pub(super) fn integer(&mut self) -> Option<u64> {
+        let value = match self.byte()? {
+            ZERO_OP => 0,
+            ONE_OP => 1,
+            ONES_OP => u64::MAX,
+            BYTE_PREFIX => self.byte()? as u64,
+            WORD_PREFIX => u16::from_le_bytes(self.take(2)?.try_into().ok()?) as u64,
+            DWORD_PREFIX => u32::from_le_bytes(self.take(4)?.try_into().ok()?) as u64,
+            QWORD_PREFIX => u64::from_le_bytes(self.take(8)?.try_into().ok()?),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Buffer holding a Generic Register descriptor
+    fn register(&mut self) -> Option<Register> {
+        if self.byte()? != BUFFER_OP {
+            return None;
+        }
+        let end = self.pkg_end()?;
+        let _size = self.integer()?;
+        let descriptor = self.data.get(self.pos..end)?;
+        self.pos = end;
+
+        if descriptor.len() < 15 || descriptor[0] != GENERIC_REGISTER_DESCRIPTOR {
+            return None;
+        }
+        Some(Register {
+            space: descriptor[3],
+            bit_width: descriptor[4],
+            bit_offset: descriptor[5],
+            access_size: descriptor[6],
+            address: u64::from_le_bytes(descriptor[7..15].try_into().ok()?),
+        })
+    }
+
+    // One state; None inside if it is entered some way not supported
+    fn state(&mut self) -> Option<Option<CState>> {
+        if self.byte()? != PACKAGE_OP {
+            return None;
+        }
+        let end = self.pkg_end()?;
+        if self.byte()? != 4 {
+            return None;
+        }
+        let register = self.register()?;
+        let kind = self.integer()? as u8;
+        let latency_us = self.integer()? as u32;
+        let power_mw = self.integer()? as u32;
+        self.pos = end;
+        Some(register.entry(kind).map(|entry| CState { kind, latency_us, power_mw, entry }))
+    }
+
+    fn package(&mut self) -> Option<Vec<CState>> {
+        if self.byte()? != PACKAGE_OP {
+            return None;
+        }
+        let _end = self.pkg_end()?;
+        let _elements = self.byte()?;
+        let count = self.integer()?;
+        let mut states = Vec::new();
+        for _ in 0..count {
+            if let Some(state) = self.state()? {
+                states.push(state);
+            }
+        }
+        Some(states)
+    }
+}
+
+// The package a `Method (_CST)` starting at `method` returns, if its body
+// returns one literally
+fn method_package(aml: &[u8], method: usize, body: usize) -> Option<Vec<CState>> {
+    let end = Aml { data: aml, pos: method + 1 }.pkg_end()?;
+    let body = aml.get(body..end)?;
+    let at = body.windows(2).position(|pair| pair == [RETURN_OP, PACKAGE_OP])?;
+    Aml { data: body, pos: at + 1 }.package()
+}
+
+/// States of the first _CST in an AML block
+pub fn parse(aml: &[u8]) -> Option<Vec<CState>> {
+    let mut from = 0;
+    while let Some(offset) = aml[from..].windows(4).position(|name| name == b"_CST") {
+        let at = from + offset;
+        from = at + 4;
+
+        // Name (_CST, Package ...)
+        if at > 0 && aml[at - 1] == NAME_OP {
+            if let Some(states) = (Aml { data: aml, pos: at + 4 }).package() {
+                return Some(states);
+            }
+            continue;
+        }
+        if let Some(method) = method_start(aml, at) {
+            if let Some(states) = method_package(aml, method, at + 5) {
+                return Some(states);
+            }
+        }
+    }
+    None
+}
+
+// Where the Method whose name is at `at` starts, if it is one: `Method
+// (NAME, flags) { ... }` puts the opcode and a PkgLength of one to four
+// bytes before the name, the flags byte after it
+pub(super) fn method_start(aml: &[u8], at: usize) -> Option<usize> {
+    (1..=4).find_map(|length_bytes| {
+        let method = at.checked_sub(length_bytes + 1)?;
+        (aml[method] == METHOD_OP && (aml[method + 1] >> 6) as usize + 1 == length_bytes).then_some(method)
+    })
+}
+
+// AML following a definition block header at physical address `address`
+unsafe fn definition_block(address: u64) -> &'static [u8] {
+    let header = (PHYS_MEM_OFFSET + address) as *const SdtHeader;
+    let length = core::ptr::addr_of!((*header).length).read_unaligned() as usize;
+    let start = (header as *const u8).add(core::mem::size_of::<SdtHeader>());
+    core::slice::from_raw_parts(start, length.saturating_sub(core::mem::size_of::<SdtHeader>()))
+}
+
+// The AML of the DSDT and each SSDT, empty if ACPI has not run
+pub(super) fn definition_blocks() -> Vec<&'static [u8]> {
+    let acpi = super::ACPI.lock();
+    let Some(fadt) = acpi.find_table(FADT_SIGNATURE) else {
+        return Vec::new();
+    };
+    let fadt = unsafe { ((PHYS_MEM_OFFSET + fadt.address) as *const super::tables::Fadt).read_unaligned() };
+    let dsdt = fadt.dsdt as u64;
+
+    let mut blocks = Vec::new();
+    if dsdt != 0 {
+        let header = unsafe { ((PHYS_MEM_OFFSET + dsdt) as *const SdtHeader).read_unaligned() };
+        if header.signature == *DSDT_SIGNATURE {
+            blocks.push(dsdt);
+        }
+    }
+    blocks.extend(acpi.tables().iter().filter(|table| table.signature == *SSDT_SIGNATURE).map(|table| table.address));
+
+    blocks.into_iter().map(|address| unsafe { definition_block(address) }).collect()
+}
+
+/// _CST states from the tables ACPI has found, None if it has not run or
+/// no table describes them
+pub fn find() -> Option<Vec<CState>> {
+    definition_blocks().into_iter().find_map(parse)
+}
@@ -0,0 +1,268 @@
+// The embedded controller, and the hotkeys it reports
+//
+// A laptop's EC signals an event, a hotkey among them, by setting SCI_EVT
+// in its status; a QR_EC command then returns the event's query number,
+// and firmware expects the `_Qxx` method of that number to run. There is
+// no AML interpreter, so query numbers are mapped to keys instead and
+// reported as input events: the query number as MSC_SCAN, then the key, or
+// KEY_UNKNOWN for a query with no key.
+//
+// Brightness keys are found in the tables. Their `_Qxx` methods end in a
+// Notify of the panel with 0x86 or 0x87, the values ACPI's video
+// extensions give brightness up and down, and a method passing either
+// byte is taken as that key. Volume and radio keys differ from vendor to
+// vendor and are mapped by hand with `hotkey map`.
+//
+// The EC's ports come from the ECDT, or are the usual 0x66 and 0x62.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use super::cst::{definition_blocks, method_start, Aml, BYTE_PREFIX};
+use super::{SdtHeader, ECDT_SIGNATURE};
+use crate::input::{self, DeviceKind, EV_MSC, KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP, KEY_UNKNOWN, MSC_SCAN};
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::time::clocksource::now_ns;
+
+const COMMAND_PORT: u16 = 0x66;
+const DATA_PORT: u16 = 0x62;
+
+// Status register
+const STATUS_OBF: u8 = 1 << 0;
+const STATUS_IBF: u8 = 1 << 1;
+const STATUS_SCI_EVT: u8 = 1 << 5;
+
+const QR_EC: u8 = 0x84;
+
+// Notify values for brightness up and down
+const NOTIFY_BRIGHTNESS_UP: u8 = 0x86;
+const NOTIFY_BRIGHTNESS_DOWN: u8 = 0x87;
+
+// ECDT: EC_CONTROL and EC_DATA, Generic Address Structures after the header
+const ECDT_CONTROL: usize = core::mem::size_of::<SdtHeader>();
+const ECDT_DATA: usize = ECDT_CONTROL + 12;
+const SPACE_SYSTEM_IO: u8 = 0x01;
+
+const TIMEOUT_US: u64 = 1000;
+// Queries taken in one poll, so a stuck SCI_EVT cannot hold the loop
+const MAX_QUERIES: usize = 8;
+const POLL_INTERVAL_NS: u64 = 20_000_000;
+
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+static PROBED: AtomicBool = AtomicBool::new(false);
+
+/// A query number and the key it stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub query: u8,
+    pub key: u16,
+    /// Found in the tables rather than mapped with `hotkey map`
+    pub from_tables: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct EcInfo {
+    pub command_port: u16,
+    pub data_port: u16,
+    pub mappings: Vec<Mapping>,
+    /// Queries that have come with no key, oldest first
+    pub unmapped: Vec<u8>,
+}
+
+struct Ec {
+    command: Port<u8>,
+    data: Port<u8>,
+    command_port: u16,
+    data_port: u16,
+    device: usize,
+    mappings: Vec<Mapping>,
+    unmapped: Vec<u8>,
+}
+
+static EC: Mutex<Option<Ec>> = Mutex::new(None);
+
+impl Ec {
+    fn status(&mut self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    fn wait(&mut self, ready: impl Fn(u8) -> bool) -> Option<()> {
+        let deadline = now_ns() + TIMEOUT_US * 1000;
+        while !ready(self.status()) {
+            if now_ns() >= deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        Some(())
+    }
+
+    // The number of the pending event, 0 if there is none
+    fn query(&mut self) -> Option<u8> {
+        self.wait(|status| status & STATUS_IBF == 0)?;
+        unsafe { self.command.write(QR_EC) };
+        self.wait(|status| status & STATUS_OBF != 0)?;
+        Some(unsafe { self.data.read() })
+    }
+
+    fn key(&self, query: u8) -> Option<u16> {
+        self.mappings.iter().find(|mapping| mapping.query == query).map(|mapping| mapping.key)
+    }
+}
+
+// Ports from the ECDT when it gives them as I/O ports
+fn ecdt_ports() -> Option<(u16, u16)> {
+    let acpi = super::ACPI.lock();
+    let table = acpi.find_table(ECDT_SIGNATURE)?;
+    if (table.length as usize) < ECDT_DATA + 12 {
+        return None;
+    }
+    let base = (PHYS_MEM_OFFSET + table.address) as *const u8;
+    let port = |offset: usize| unsafe {
+        let space = base.add(offset).read();
+        let address = (base.add(offset + 4) as *const u64).read_unaligned();
+        (space == SPACE_SYSTEM_IO && address != 0 && address <= u16::MAX as u64).then_some(address as u16)
+    };
+    Some((port(ECDT_CONTROL)?, port(ECDT_DATA)?))
+}
+
+// `_Qxx` methods that pass a brightness notification on
+fn brightness_queries(aml: &[u8], mappings: &mut Vec<Mapping>) {
+    let mut from = 0;
+    while let Some(offset) = aml[from..].windows(2).position(|name| name == b"_Q") {
+        let at = from + offset;
+        from = at + 2;
+        let Some(query) = aml
+            .get(at + 2..at + 4)
+            .and_then(|digits| core::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        else {
+            continue;
+        };
+        let Some(end) = method_start(aml, at).and_then(|method| Aml { data: aml, pos: method + 1 }.pkg_end()) else {
+            continue;
+        };
+        let Some(body) = aml.get(at + 5..end) else {
+            continue;
+        };
+        let key = if body.windows(2).any(|pair| pair == [BYTE_PREFIX, NOTIFY_BRIGHTNESS_UP]) {
+            KEY_BRIGHTNESSUP
+        } else if body.windows(2).any(|pair| pair == [BYTE_PREFIX, NOTIFY_BRIGHTNESS_DOWN]) {
+            KEY_BRIGHTNESSDOWN
+        } else {
+            continue;
+        };
+        if !mappings.iter().any(|mapping| mapping.query == query) {
+            mappings.push(Mapping { query, key, from_tables: true });
+        }
+    }
+}
+
+fn probe() -> Option<Ec> {
+    let (command_port, data_port) = ecdt_ports().unwrap_or((COMMAND_PORT, DATA_PORT));
+    let mut command = Port::<u8>::new(command_port);
+    // Nothing decodes the port
+    if unsafe { command.read() } == 0xFF {
+        return None;
+    }
+    let mut mappings = Vec::new();
+    for aml in definition_blocks() {
+        brightness_queries(aml, &mut mappings);
+    }
+    mappings.sort_by_key(|mapping| mapping.query);
+    let device = input::register("ACPI EC hotkeys", DeviceKind::Hotkeys);
+    crate::serial_println!(
+        "acpi-ec: ports 0x{:x}/0x{:x}, {} brightness queries in the tables",
+        command_port,
+        data_port,
+        mappings.len()
+    );
+    Some(Ec {
+        command,
+        data: Port::new(data_port),
+        command_port,
+        data_port,
+        device,
+        mappings,
+        unmapped: Vec::new(),
+    })
+}
+
+// Find the EC the first time it is wanted, once ACPI has read the tables
+fn ensure_probed() {
+    if !PROBED.swap(true, Ordering::Relaxed) {
+        *EC.lock() = probe();
+    }
+}
+
+/// Take the EC's pending events and report their keys; called from the
+/// main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    ensure_probed();
+
+    let mut events = Vec::new();
+    let device = {
+        let mut ec = EC.lock();
+        let Some(ec) = ec.as_mut() else {
+            return;
+        };
+        for _ in 0..MAX_QUERIES {
+            if ec.status() & STATUS_SCI_EVT == 0 {
+                break;
+            }
+            let Some(query) = ec.query().filter(|&query| query != 0) else {
+                break;
+            };
+            let key = ec.key(query);
+            if key.is_none() && !ec.unmapped.contains(&query) {
+                crate::serial_println!("acpi-ec: query 0x{:02X} has no key", query);
+                ec.unmapped.push(query);
+            }
+            events.push((query, key.unwrap_or(KEY_UNKNOWN)));
+        }
+        ec.device
+    };
+    // Outside the lock: keys the kernel acts on may take a while
+    for (query, key) in events {
+        input::report(device, EV_MSC, MSC_SCAN, query as i32);
+        input::key(device, key, true);
+        input::sync(device);
+        input::key(device, key, false);
+        input::sync(device);
+    }
+}
+
+/// Map a query number to a key, or with None take its mapping away
+pub fn map(query: u8, key: Option<u16>) -> Result<(), &'static str> {
+    ensure_probed();
+    let mut ec = EC.lock();
+    let ec = ec.as_mut().ok_or("no embedded controller")?;
+    ec.mappings.retain(|mapping| mapping.query != query);
+    if let Some(key) = key {
+        ec.mappings.push(Mapping { query, key, from_tables: false });
+        ec.mappings.sort_by_key(|mapping| mapping.query);
+        ec.unmapped.retain(|&unmapped| unmapped != query);
+    }
+    Ok(())
+}
+
+/// The EC's ports and mappings, None if there is no EC
+pub fn info() -> Option<EcInfo> {
+    ensure_probed();
+    let ec = EC.lock();
+    let ec = ec.as_ref()?;
+    Some(EcInfo {
+        command_port: ec.command_port,
+        data_port: ec.data_port,
+        mappings: ec.mappings.clone(),
+        unmapped: ec.unmapped.clone(),
+    })
+}
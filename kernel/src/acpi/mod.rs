@@ -3,6 +3,8 @@ pub mod tables;
 pub mod power;
 pub mod apic;
 pub mod pci;
+pub mod cst;
+pub mod ec;
 
 use crate::{println, serial_println};
 
@@ -24,6 +26,7 @@ pub const HPET_SIGNATURE: &[u8; 4] = b"HPET";
 pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
 pub const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
 pub const SSDT_SIGNATURE: &[u8; 4] = b"SSDT";
+pub const ECDT_SIGNATURE: &[u8; 4] = b"ECDT";
 
 // RSDP (Root System Description Pointer) Structure
 #[repr(C, packed)]
@@ -115,6 +118,15 @@ impl AcpiManager {
     }
     
     fn find_rsdp(&mut self) -> Result<(), &'static str> {
+        // The loader's copy, which UEFI systems have no BIOS areas for
+        if let Some(rsdp) = crate::boot::info().and_then(|info| info.rsdp) {
+            let ptr = (PHYS_MEM_OFFSET + rsdp) as *const Rsdp;
+            if unsafe { (*ptr).signature == *RSDP_SIGNATURE } {
+                self.rsdp = Some(ptr as u64);
+                return Ok(());
+            }
+        }
+
         // Search for RSDP in BIOS areas
         // First search in EBDA (Extended BIOS Data Area)
         if let Some(rsdp) = self.search_rsdp(0x00080000, 0x000A0000) {
@@ -293,6 +305,9 @@ impl AcpiManager {
         unsafe {
             // Store power management addresses
             power::init_fadt(fadt)?;
+
+            // CMOS register holding the RTC's century
+            crate::time::rtc::set_century_register((*fadt).century);
             
             // Load DSDT
             let dsdt_addr = (*fadt).dsdt;
@@ -316,7 +331,10 @@ impl AcpiManager {
     
     fn process_hpet(&mut self, table: *const SdtHeader) -> Result<(), &'static str> {
         // Process High Precision Event Timer
-        serial_println!("ACPI: Found HPET table");
+        let hpet = unsafe { (table as *const tables::Hpet).read_unaligned() };
+        let address = hpet.base_address.address;
+        serial_println!("ACPI: Found HPET at 0x{:x}", address);
+        crate::time::hpet::set_base(address);
         Ok(())
     }
     
@@ -363,6 +381,10 @@ impl AcpiManager {
     pub fn find_table(&self, signature: &[u8; 4]) -> Option<&AcpiTable> {
         self.tables.iter().find(|t| &t.signature == signature)
     }
+    
+    pub fn tables(&self) -> &[AcpiTable] {
+        &self.tables
+    }
 }
 
 lazy_static! {
@@ -276,6 +276,11 @@ pub fn parse_mcfg(table: *const SdtHeader) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Where a segment's configuration space is mapped, if MCFG describes it
+pub fn segment_base(segment: u16) -> Option<u64> {
+    PCI_SEGMENTS.lock().iter().find(|s| s.segment == segment).map(|s| s.base_address)
+}
+
 pub fn enumerate_all_devices() -> Vec<PciDevice> {
     let mut all_devices = Vec::new();
     let segments = PCI_SEGMENTS.lock();
@@ -1,10 +1,44 @@
 // ACPI Power Management Implementation
-use super::tables::Fadt;
+//
+// Powering off and resetting take no locks, so a shutdown can finish from
+// the timer interrupt when a driver hangs on the way down. The registers
+// they write are kept in FIXED rather than POWER_MGMT for that reason.
+use super::cst::{Aml, NAME_OP, PACKAGE_OP};
+use super::tables::{Fadt, GenericAddressStructure, FADT_RESET_REG, FADT_RESET_REG_SUP, FADT_RESET_VALUE};
 use x86_64::instructions::port::Port;
-use spin::Mutex;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use spin::{Mutex, Once};
 use lazy_static::lazy_static;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::time::clocksource::now_ns;
 use crate::{println, serial_println};
 
+// PM1 control register
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+// Generic Address Structure spaces a reset register may be in
+const SPACE_SYSTEM_MEMORY: u8 = 0x00;
+const SPACE_SYSTEM_IO: u8 = 0x01;
+const SPACE_PCI_CONFIG: u8 = 0x02;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+// The chipset's reset control register: a full reset as CPU reset goes
+// from 0 to 1
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_CONTROL_FULL: u8 = 0x02;
+const RESET_CONTROL_CPU: u8 = 0x04;
+
+const KBC_COMMAND: u16 = 0x64;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+// How long each way of powering off or resetting is given to work
+const SETTLE_NS: u64 = 500_000_000;
+
 // Power Management Registers
 pub struct PowerManagement {
     pm1a_control: Option<Port<u16>>,
@@ -16,7 +50,6 @@ pub struct PowerManagement {
     acpi_enable: u8,
     acpi_disable: u8,
     sleep_type_s3: Option<u8>,
-    sleep_type_s5: Option<u8>,
 }
 
 impl PowerManagement {
@@ -31,7 +64,6 @@ impl PowerManagement {
             acpi_enable: 0,
             acpi_disable: 0,
             sleep_type_s3: None,
-            sleep_type_s5: None,
         }
     }
     
@@ -69,7 +101,6 @@ impl PowerManagement {
         // Parse DSDT/SSDT for sleep states
         // For now, use default values
         self.sleep_type_s3 = Some(5);  // Common S3 value
-        self.sleep_type_s5 = Some(7);  // Common S5 value
     }
     
     pub fn enable_acpi(&mut self) -> Result<(), &'static str> {
@@ -113,35 +144,6 @@ impl PowerManagement {
         }
     }
     
-    pub fn shutdown(&mut self) -> Result<(), &'static str> {
-        let sleep_type = self.sleep_type_s5.ok_or("S5 sleep type not available")?;
-        
-        // Prepare S5 shutdown
-        let sleep_val = (sleep_type as u16) << 10;
-        let sleep_enable = 1u16 << 13;
-        
-        // Write to PM1A control
-        if let Some(ref mut pm1a) = self.pm1a_control {
-            unsafe {
-                pm1a.write(sleep_val | sleep_enable);
-            }
-        } else {
-            return Err("PM1A control not available");
-        }
-        
-        // Write to PM1B control if available
-        if let Some(ref mut pm1b) = self.pm1b_control {
-            unsafe {
-                pm1b.write(sleep_val | sleep_enable);
-            }
-        }
-        
-        // System should power off now
-        loop {
-            x86_64::instructions::hlt();
-        }
-    }
-    
     pub fn suspend_to_ram(&mut self) -> Result<(), &'static str> {
         let sleep_type = self.sleep_type_s3.ok_or("S3 sleep type not available")?;
         
@@ -224,6 +226,93 @@ lazy_static! {
     static ref POWER_MGMT: Mutex<PowerManagement> = Mutex::new(PowerManagement::new());
 }
 
+// What powering off and resetting write
+struct Fixed {
+    pm1a_control: Option<u16>,
+    pm1b_control: Option<u16>,
+    reset: Option<(GenericAddressStructure, u8)>,
+}
+
+static FIXED: Once<Fixed> = Once::new();
+static SLEEP_TYPE_S5: Once<Option<(u8, u8)>> = Once::new();
+
+// The reset register and its value, if the FADT is new enough to have one
+// and says it works
+unsafe fn reset_register(fadt: *const Fadt) -> Option<(GenericAddressStructure, u8)> {
+    let length = core::ptr::addr_of!((*fadt).header.length).read_unaligned() as usize;
+    let flags = core::ptr::addr_of!((*fadt).flags).read_unaligned();
+    if length <= FADT_RESET_VALUE || flags & FADT_RESET_REG_SUP == 0 {
+        return None;
+    }
+    let base = fadt as *const u8;
+    let register = (base.add(FADT_RESET_REG) as *const GenericAddressStructure).read_unaligned();
+    Some((register, base.add(FADT_RESET_VALUE).read()))
+}
+
+// SLP_TYPa and SLP_TYPb from `Name (\_S5_, Package () { a, b, ... })`,
+// or the same for another sleep state
+fn sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    let mut from = 0;
+    while let Some(offset) = aml[from..].windows(4).position(|window| window == name) {
+        let at = from + offset;
+        from = at + 4;
+
+        // The name may be given from the root
+        let name_op = match at.checked_sub(1) {
+            Some(root) if aml[root] == b'\\' => root.checked_sub(1),
+            before => before,
+        };
+        if name_op.map(|op| aml[op]) != Some(NAME_OP) {
+            continue;
+        }
+        let mut package = Aml { data: aml, pos: at + 4 };
+        if package.byte() != Some(PACKAGE_OP) || package.pkg_end().is_none() || package.byte().is_none() {
+            continue;
+        }
+        if let (Some(a), Some(b)) = (package.integer(), package.integer()) {
+            return Some((a as u8, b as u8));
+        }
+    }
+    None
+}
+
+/// SLP_TYPa and SLP_TYPb for S5, found in the DSDT or an SSDT the first
+/// time they are asked for
+pub fn sleep_type_s5() -> Option<(u8, u8)> {
+    *SLEEP_TYPE_S5.call_once(|| {
+        let found = super::cst::definition_blocks().into_iter().find_map(|aml| sleep_type(aml, b"_S5_"));
+        if found.is_none() {
+            serial_println!("ACPI: No \\_S5_ object; cannot power off");
+        }
+        found
+    })
+}
+
+// Give the machine time to go away
+fn settle() {
+    let deadline = now_ns() + SETTLE_NS;
+    while now_ns() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn write_register(register: &GenericAddressStructure, value: u8) {
+    let address = register.address;
+    match register.address_space {
+        SPACE_SYSTEM_MEMORY => ((PHYS_MEM_OFFSET + address) as *mut u8).write_volatile(value),
+        SPACE_SYSTEM_IO => Port::<u8>::new(address as u16).write(value),
+        SPACE_PCI_CONFIG => {
+            // Bus 0; the device, function and offset are packed in the address
+            let device = (address >> 32) as u32 & 0x1F;
+            let function = (address >> 16) as u32 & 0x7;
+            let offset = address as u32 & 0xFF;
+            Port::<u32>::new(PCI_CONFIG_ADDRESS).write(0x8000_0000 | device << 11 | function << 8 | offset & !3);
+            Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+        }
+        _ => {}
+    }
+}
+
 pub fn init() -> Result<(), &'static str> {
     // Power management is initialized from FADT
     Ok(())
@@ -232,6 +321,12 @@ pub fn init() -> Result<(), &'static str> {
 pub fn init_fadt(fadt: *const Fadt) -> Result<(), &'static str> {
     unsafe {
         POWER_MGMT.lock().init_from_fadt(&*fadt);
+        let port = |block: u32| (block != 0).then_some(block as u16);
+        FIXED.call_once(|| Fixed {
+            pm1a_control: port((*fadt).pm1a_control_block),
+            pm1b_control: port((*fadt).pm1b_control_block),
+            reset: reset_register(fadt),
+        });
     }
     
     // Enable ACPI if not already enabled
@@ -243,7 +338,29 @@ pub fn init_fadt(fadt: *const Fadt) -> Result<(), &'static str> {
 
 pub fn shutdown() -> Result<(), &'static str> {
     serial_println!("ACPI: Initiating system shutdown");
-    POWER_MGMT.lock().shutdown()
+    sleep_type_s5().ok_or("S5 sleep type not available")?;
+    power_off()
+}
+
+/// Enter S5 with the sleep type `sleep_type_s5` found. Returns only if
+/// there is no way to, or the machine is still running afterwards.
+pub fn power_off() -> Result<(), &'static str> {
+    let (type_a, type_b) = SLEEP_TYPE_S5.get().copied().flatten().ok_or("S5 sleep type not available")?;
+    let fixed = FIXED.get().ok_or("PM1A control not available")?;
+    let pm1a = fixed.pm1a_control.ok_or("PM1A control not available")?;
+
+    let enter = |port: u16, sleep_type: u8| unsafe {
+        let mut control = Port::<u16>::new(port);
+        let value = control.read() & !SLP_TYP_MASK;
+        control.write(value | (sleep_type as u16) << SLP_TYP_SHIFT | SLP_EN);
+    };
+    if let Some(pm1b) = fixed.pm1b_control {
+        enter(pm1b, type_b);
+    }
+    enter(pm1a, type_a);
+
+    settle();
+    Err("S5 entry did not power off")
 }
 
 pub fn suspend_to_ram() -> Result<(), &'static str> {
@@ -251,20 +368,32 @@ pub fn suspend_to_ram() -> Result<(), &'static str> {
     POWER_MGMT.lock().suspend_to_ram()
 }
 
-pub fn reboot() -> Result<(), &'static str> {
-    // Try ACPI reset first
-    // If that fails, use keyboard controller or triple fault
-    
-    // Keyboard controller reset
+/// Reset the machine: through the FADT's reset register, then the reset
+/// control register, then the keyboard controller, and last by a triple
+/// fault
+pub fn reboot() -> ! {
+    if let Some((register, value)) = FIXED.get().and_then(|fixed| fixed.reset) {
+        unsafe { write_register(&register, value) };
+        settle();
+    }
+
     unsafe {
-        let mut kbd = Port::<u8>::new(0x64);
-        kbd.write(0xFE);
+        let mut reset_control = Port::<u8>::new(RESET_CONTROL);
+        reset_control.write(RESET_CONTROL_FULL);
+        reset_control.write(RESET_CONTROL_FULL | RESET_CONTROL_CPU);
     }
-    
-    // Triple fault as last resort
+    settle();
+
+    unsafe { Port::<u8>::new(KBC_COMMAND).write(KBC_PULSE_RESET) };
+    settle();
+
+    // No vectors at all, so the breakpoint cannot be delivered
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
     unsafe {
-        core::arch::asm!("lidt [0]", "int3");
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
     }
-    
-    unreachable!()
-}
\ No newline at end of file
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -46,6 +46,10 @@ pub struct Fadt {
     // ACPI 2.0+ fields follow (GenericAddressStructure fields)
 }
 
+// Offsets of the ACPI 2.0 reset register and the value written to it
+pub const FADT_RESET_REG: usize = 116;
+pub const FADT_RESET_VALUE: usize = 128;
+
 // Generic Address Structure
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -12,6 +12,8 @@ use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, RwLock};
 use lazy_static::lazy_static;
 
+pub mod heap_debug;
+
 // Constants for memory management
 pub const HEAP_SIZE: usize = 32 * 1024 * 1024; // 32 MiB heap for better performance
 pub const PAGE_SIZE: usize = 4096;
@@ -684,6 +686,24 @@ impl HybridAllocator {
 
 unsafe impl GlobalAlloc for HybridAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if heap_debug::enabled() {
+            heap_debug::alloc(self, layout)
+        } else {
+            self.raw_alloc(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if heap_debug::enabled() && !ptr.is_null() {
+            heap_debug::dealloc(self, ptr, layout)
+        } else {
+            self.raw_dealloc(ptr, layout)
+        }
+    }
+}
+
+impl HybridAllocator {
+    unsafe fn raw_alloc(&self, layout: Layout) -> *mut u8 {
         // Initialize on first allocation
         if self.initialized.load(Ordering::Relaxed) == 0 {
             self.init();
@@ -718,7 +738,7 @@ unsafe impl GlobalAlloc for HybridAllocator {
         ptr::null_mut()
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn raw_dealloc(&self, ptr: *mut u8, layout: Layout) {
         if ptr.is_null() {
             return;
         }
@@ -765,6 +785,21 @@ pub fn init_heap() {
     }
 }
 
+/// Whether some CPU is inside the allocator. NMI handlers, which may have
+/// interrupted the allocator on their own CPU, check before allocating.
+pub fn is_busy() -> bool {
+    ALLOCATOR.buddy_allocator.is_locked()
+        || ALLOCATOR.slab_allocator.writer_count() > 0
+        || ALLOCATOR.slab_allocator.reader_count() > 0
+        || ALLOCATOR.cpu_caches.iter().any(|cache| cache.is_locked())
+        || (heap_debug::enabled() && heap_debug::is_locked())
+}
+
+/// Start address and size of the kernel heap
+pub fn heap_range() -> (u64, u64) {
+    (core::ptr::addr_of!(HEAP) as u64, HEAP_SIZE as u64)
+}
+
 // Memory statistics functions
 pub fn memory_stats() -> MemoryStats {
     let buddy = ALLOCATOR.buddy_allocator.lock();
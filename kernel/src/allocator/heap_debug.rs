@@ -0,0 +1,501 @@
+// Heap Debugging
+// With the heap-debug feature every kernel heap allocation goes through here
+// (KASAN/SLUB-debug style, without compiler instrumentation):
+//
+//   [ redzone | header | guard ][ object ][ redzone ]
+//
+// Redzones are filled with a known byte and checked when the object is freed
+// and by verify(), catching writes past either end. Freed objects are
+// poisoned and held in a quarantine before going back to the allocator; the
+// poison is checked on eviction, catching writes after free, and the header
+// catches double and invalid frees. Live objects are linked together with
+// the call chain that allocated them, so everything allocated since a
+// checkpoint and never freed can be reported as a leak.
+
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::HybridAllocator;
+use crate::debug::unwind::{self, Frame};
+
+const LIVE: u64 = 0x4556_494c_5041_4548; // "HEAPLIVE"
+const FREED: u64 = 0x4545_5246_5041_4548; // "HEAPFREE"
+
+const REDZONE: u8 = 0xfc;
+const ALLOC_POISON: u8 = 0xa5;
+const FREE_POISON: u8 = 0x6b;
+
+const GUARD: usize = 16;
+const CALLERS: usize = 12;
+const QUARANTINE_ENTRIES: usize = 4096;
+const QUARANTINE_BYTES: usize = 4 * 1024 * 1024;
+const EVICT_BATCH: usize = 8;
+const MAX_PROBLEMS: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+    sequence: u64,
+    prev: *mut Header,
+    next: *mut Header,
+    allocated_by: [u64; CALLERS],
+    freed_by: [u64; CALLERS],
+}
+
+struct State {
+    live: *mut Header,
+    live_count: usize,
+    live_bytes: usize,
+    sequence: u64,
+    quarantine: [*mut Header; QUARANTINE_ENTRIES],
+    quarantine_head: usize,
+    quarantine_count: usize,
+    quarantine_bytes: usize,
+}
+
+// The headers are only reached through the state lock
+unsafe impl Send for State {}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    live: ptr::null_mut(),
+    live_count: 0,
+    live_bytes: 0,
+    sequence: 0,
+    quarantine: [ptr::null_mut(); QUARANTINE_ENTRIES],
+    quarantine_head: 0,
+    quarantine_count: 0,
+    quarantine_bytes: 0,
+});
+
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+static CHECKPOINT: AtomicU64 = AtomicU64::new(0);
+
+pub fn enabled() -> bool {
+    cfg!(feature = "heap-debug")
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    // The allocator is used from interrupt handlers too
+    interrupts::without_interrupts(|| f(&mut STATE.lock()))
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// Layout of the whole block and the offset of the object within it
+fn block_layout(size: usize, align: usize) -> Option<(Layout, usize)> {
+    let align = align.max(16);
+    let offset = round_up(size_of::<Header>() + GUARD, align);
+    let total = offset.checked_add(round_up(size, 16))?.checked_add(GUARD)?;
+    Some((Layout::from_size_align(total, align).ok()?, offset))
+}
+
+unsafe fn header_of(object: *mut u8) -> *mut Header {
+    object.sub(GUARD + size_of::<Header>()) as *mut Header
+}
+
+unsafe fn object_of(header: *mut Header) -> *mut u8 {
+    (header as *mut u8).add(size_of::<Header>() + GUARD)
+}
+
+// Return addresses of the current call chain, walked by frame pointer
+#[inline(always)]
+fn callers() -> [u64; CALLERS] {
+    let mut chain = [0; CALLERS];
+    let mut fp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+
+    for slot in chain.iter_mut() {
+        if fp == 0 || fp % 8 != 0 || !crate::debug::is_mapped(fp) || !crate::debug::is_mapped(fp + 8) {
+            break;
+        }
+        let (next, return_address) = unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+        *slot = return_address;
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    chain
+}
+
+pub(super) fn is_locked() -> bool {
+    STATE.is_locked()
+}
+
+pub(super) unsafe fn alloc(inner: &HybridAllocator, layout: Layout) -> *mut u8 {
+    let Some((block_layout, offset)) = block_layout(layout.size(), layout.align()) else {
+        return ptr::null_mut();
+    };
+    let allocated_by = callers();
+
+    let block = inner.raw_alloc(block_layout);
+    if block.is_null() {
+        return block;
+    }
+    let object = block.add(offset);
+    let header = header_of(object);
+
+    ptr::write_bytes(block, REDZONE, header as usize - block as usize);
+    ptr::write_bytes(object.sub(GUARD), REDZONE, GUARD);
+    ptr::write_bytes(object, ALLOC_POISON, layout.size());
+    ptr::write_bytes(object.add(layout.size()), REDZONE, block_layout.size() - offset - layout.size());
+
+    with_state(|state| {
+        state.sequence += 1;
+        header.write(Header {
+            magic: LIVE,
+            size: layout.size(),
+            align: layout.align(),
+            sequence: state.sequence,
+            prev: ptr::null_mut(),
+            next: state.live,
+            allocated_by,
+            freed_by: [0; CALLERS],
+        });
+        if !state.live.is_null() {
+            (*state.live).prev = header;
+        }
+        state.live = header;
+        state.live_count += 1;
+        state.live_bytes += layout.size();
+    });
+    object
+}
+
+enum Free {
+    Freed(Header, Option<(isize, u8)>, [*mut Header; EVICT_BATCH]),
+    Double(Header),
+    Invalid,
+}
+
+pub(super) unsafe fn dealloc(inner: &HybridAllocator, object: *mut u8, layout: Layout) {
+    let header = header_of(object);
+    let freed_by = callers();
+
+    if !crate::debug::is_mapped(header as u64) || !crate::debug::is_mapped(object as u64 - 1) {
+        report_invalid_free(object, layout, &freed_by);
+        return;
+    }
+
+    let outcome = with_state(|state| match (*header).magic {
+        LIVE => {
+            let (prev, next, size) = ((*header).prev, (*header).next, (*header).size);
+            if prev.is_null() {
+                state.live = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            state.live_count -= 1;
+            state.live_bytes -= size;
+            (*header).magic = FREED;
+            (*header).freed_by = freed_by;
+            // Checked and poisoned before it enters the quarantine, where
+            // another CPU may evict it
+            let redzone = check_redzones(header);
+            ptr::write_bytes(object, FREE_POISON, size);
+            Free::Freed(*header, redzone, quarantine(state, header))
+        }
+        FREED => Free::Double(*header),
+        _ => Free::Invalid,
+    });
+
+    let (entry, redzone, evicted) = match outcome {
+        Free::Freed(entry, redzone, evicted) => (entry, redzone, evicted),
+        Free::Double(header) => {
+            report_double_free(object, &header, &freed_by);
+            return;
+        }
+        Free::Invalid => {
+            report_invalid_free(object, layout, &freed_by);
+            return;
+        }
+    };
+
+    if entry.size != layout.size() || entry.align != layout.align() {
+        report_header("free with mismatched layout", object, &entry);
+        crate::serial_println!("[HEAPDBG] Allocated as {} bytes align {}, freed as {} bytes align {}",
+            entry.size, entry.align, layout.size(), layout.align());
+        report_chains(&entry);
+    }
+    if let Some((position, value)) = redzone {
+        report_out_of_bounds(object, &entry, position, value);
+    }
+
+    for &old in evicted.iter().take_while(|old| !old.is_null()) {
+        release(inner, old);
+    }
+}
+
+// Queue a freed object and take out the oldest ones beyond the budget, for
+// the caller to release once the lock is dropped
+unsafe fn quarantine(state: &mut State, header: *mut Header) -> [*mut Header; EVICT_BATCH] {
+    let mut evicted = [ptr::null_mut(); EVICT_BATCH];
+    let mut count = 0;
+    while count < EVICT_BATCH
+        && state.quarantine_count > 0
+        && (state.quarantine_count == QUARANTINE_ENTRIES || state.quarantine_bytes + (*header).size > QUARANTINE_BYTES)
+    {
+        let oldest = state.quarantine[state.quarantine_head];
+        state.quarantine_head = (state.quarantine_head + 1) % QUARANTINE_ENTRIES;
+        state.quarantine_count -= 1;
+        state.quarantine_bytes -= (*oldest).size;
+        evicted[count] = oldest;
+        count += 1;
+    }
+
+    let tail = (state.quarantine_head + state.quarantine_count) % QUARANTINE_ENTRIES;
+    state.quarantine[tail] = header;
+    state.quarantine_count += 1;
+    state.quarantine_bytes += (*header).size;
+    evicted
+}
+
+// Check an object leaving the quarantine and give it back to the allocator
+unsafe fn release(inner: &HybridAllocator, header: *mut Header) {
+    let entry = *header;
+    let object = object_of(header);
+    if let Some(offset) = (0..entry.size).find(|&i| *object.add(i) != FREE_POISON) {
+        report_use_after_free(object, &entry, offset, *object.add(offset));
+    }
+    if let Some((position, value)) = check_redzones(header) {
+        report_out_of_bounds(object, &entry, position, value);
+    }
+
+    if let Some((layout, offset)) = block_layout(entry.size, entry.align) {
+        (*header).magic = 0;
+        inner.raw_dealloc(object.sub(offset), layout);
+    }
+}
+
+// First redzone byte that was overwritten, as an offset from the object
+unsafe fn check_redzones(header: *mut Header) -> Option<(isize, u8)> {
+    let entry = &*header;
+    let object = object_of(header);
+    let (layout, offset) = block_layout(entry.size, entry.align)?;
+    let block = object.sub(offset);
+
+    let left = (0..header as usize - block as usize).chain(offset - GUARD..offset);
+    let right = offset + entry.size..layout.size();
+    left.chain(right)
+        .find(|&i| *block.add(i) != REDZONE)
+        .map(|i| (i as isize - offset as isize, *block.add(i)))
+}
+
+/// Check the redzones of every live object and the poison of every
+/// quarantined one; returns the number of corrupted objects
+pub fn verify() -> usize {
+    if !enabled() {
+        return 0;
+    }
+
+    // Reports allocate, so collect problems first and report after
+    // dropping the lock
+    let mut problems: [Option<(Header, *mut u8, bool, isize, u8)>; MAX_PROBLEMS] = [None; MAX_PROBLEMS];
+    let mut count = 0;
+    with_state(|state| unsafe {
+        let mut header = state.live;
+        while !header.is_null() {
+            if let Some((position, value)) = check_redzones(header) {
+                if count < MAX_PROBLEMS {
+                    problems[count] = Some((*header, object_of(header), false, position, value));
+                }
+                count += 1;
+            }
+            header = (*header).next;
+        }
+
+        for i in 0..state.quarantine_count {
+            let header = state.quarantine[(state.quarantine_head + i) % QUARANTINE_ENTRIES];
+            let object = object_of(header);
+            if let Some(offset) = (0..(*header).size).find(|&i| *object.add(i) != FREE_POISON) {
+                if count < MAX_PROBLEMS {
+                    problems[count] = Some((*header, object, true, offset as isize, *object.add(offset)));
+                }
+                count += 1;
+            }
+        }
+    });
+
+    for (entry, object, freed, position, value) in problems.iter().flatten() {
+        let (entry, object, position, value) = (entry, *object, *position, *value);
+        if *freed {
+            report_use_after_free(object, &entry, position as usize, value);
+        } else {
+            report_out_of_bounds(object, &entry, position, value);
+        }
+    }
+    if count > MAX_PROBLEMS {
+        crate::serial_println!("[HEAPDBG] ... and {} more corrupted objects", count - MAX_PROBLEMS);
+    }
+    count
+}
+
+/// Start a leak-check window; returns the checkpoint
+pub fn checkpoint() -> u64 {
+    let sequence = with_state(|state| state.sequence);
+    CHECKPOINT.store(sequence, Ordering::Relaxed);
+    sequence
+}
+
+pub fn last_checkpoint() -> u64 {
+    CHECKPOINT.load(Ordering::Relaxed)
+}
+
+struct Leak {
+    size: usize,
+    sequence: u64,
+    allocated_by: [u64; CALLERS],
+}
+
+/// Report objects allocated after `since` that are still live, grouped by
+/// allocating call chain; returns the number of leaked objects
+pub fn report_leaks(since: u64) -> usize {
+    if !enabled() {
+        return 0;
+    }
+
+    // Allocate the snapshot up front so the walk does not allocate
+    let capacity = with_state(|state| state.live_count) + 64;
+    let mut leaks: Vec<Leak> = Vec::with_capacity(capacity);
+    let own = leaks.as_ptr() as *mut u8;
+    let mut missed = 0;
+    with_state(|state| unsafe {
+        let mut header = state.live;
+        while !header.is_null() {
+            let entry = &*header;
+            if entry.sequence > since && object_of(header) != own {
+                if leaks.len() < leaks.capacity() {
+                    leaks.push(Leak { size: entry.size, sequence: entry.sequence, allocated_by: entry.allocated_by });
+                } else {
+                    missed += 1;
+                }
+            }
+            header = entry.next;
+        }
+    });
+
+    if leaks.is_empty() && missed == 0 {
+        crate::serial_println!("[HEAPDBG] No leaks since checkpoint {}", since);
+        return 0;
+    }
+
+    // One group per call chain, largest first
+    leaks.sort_unstable_by(|a, b| a.allocated_by.cmp(&b.allocated_by).then(a.sequence.cmp(&b.sequence)));
+    let mut groups: Vec<(usize, usize, &Leak)> = Vec::new();
+    for leak in &leaks {
+        match groups.last_mut() {
+            Some((count, bytes, first)) if first.allocated_by == leak.allocated_by => {
+                *count += 1;
+                *bytes += leak.size;
+            }
+            _ => groups.push((1, leak.size, leak)),
+        }
+    }
+    groups.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+    let total: usize = leaks.iter().map(|leak| leak.size).sum();
+    crate::serial_println!("[HEAPDBG] {} objects ({} bytes) allocated since checkpoint {} are still live:",
+        leaks.len() + missed, total, since);
+    for (count, bytes, first) in groups.iter().take(20) {
+        crate::serial_println!("[HEAPDBG] {} object(s), {} bytes, first #{}, allocated by:", count, bytes, first.sequence);
+        print_chain(&first.allocated_by);
+    }
+    if groups.len() > 20 {
+        crate::serial_println!("[HEAPDBG] ... and {} more call sites", groups.len() - 20);
+    }
+    leaks.len() + missed
+}
+
+pub fn print_statistics() {
+    if !enabled() {
+        crate::serial_println!("[HEAPDBG] Heap debugging is not built in (heap-debug feature)");
+        return;
+    }
+    let (live, bytes, quarantined, quarantine_bytes, sequence) = with_state(|state| {
+        (state.live_count, state.live_bytes, state.quarantine_count, state.quarantine_bytes, state.sequence)
+    });
+    crate::serial_println!("[HEAPDBG] Live: {} objects, {} bytes", live, bytes);
+    crate::serial_println!("[HEAPDBG] Quarantine: {} objects, {} of {} bytes", quarantined, quarantine_bytes, QUARANTINE_BYTES);
+    crate::serial_println!("[HEAPDBG] Allocations: {}, last checkpoint #{}", sequence, last_checkpoint());
+    crate::serial_println!("[HEAPDBG] Errors reported: {}", REPORTS.load(Ordering::Relaxed));
+}
+
+// Frames inside the allocator and the alloc crate say nothing about who
+// allocated
+fn is_allocator_frame(symbol: &str) -> bool {
+    symbol.starts_with("__r")
+        || symbol.starts_with("alloc::")
+        || symbol.starts_with("<alloc::")
+        || symbol.contains("allocator::")
+}
+
+fn print_chain(chain: &[u64; CALLERS]) {
+    let frames: Vec<Frame> = chain
+        .iter()
+        .take_while(|&&pc| pc != 0)
+        .map(|&pc| Frame { return_address: true, ..Frame::new(pc, 0, 0) })
+        .skip_while(|frame| is_allocator_frame(&frame.symbol()))
+        .collect();
+    unwind::print_backtrace(&frames);
+}
+
+fn report_header(title: &str, object: *mut u8, entry: &Header) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    crate::serial_println!("[HEAPDBG] ==================================================");
+    crate::serial_println!("[HEAPDBG] BUG: {} on {}-byte object {:#x} (#{})", title, entry.size, object as u64, entry.sequence);
+}
+
+fn report_chains(entry: &Header) {
+    crate::serial_println!("[HEAPDBG] Allocated by:");
+    print_chain(&entry.allocated_by);
+    if entry.freed_by[0] != 0 {
+        crate::serial_println!("[HEAPDBG] Freed by:");
+        print_chain(&entry.freed_by);
+    }
+}
+
+fn report_out_of_bounds(object: *mut u8, entry: &Header, position: isize, value: u8) {
+    report_header("out-of-bounds write", object, entry);
+    crate::serial_println!("[HEAPDBG] Redzone byte at object{:+} is {:#04x}, expected {:#04x}", position, value, REDZONE);
+    report_chains(entry);
+}
+
+fn report_use_after_free(object: *mut u8, entry: &Header, offset: usize, value: u8) {
+    report_header("use-after-free write", object, entry);
+    crate::serial_println!("[HEAPDBG] Freed byte at object+{} is {:#04x}, expected {:#04x}", offset, value, FREE_POISON);
+    report_chains(entry);
+}
+
+fn report_double_free(object: *mut u8, entry: &Header, freed_by: &[u64; CALLERS]) {
+    report_header("double free", object, entry);
+    report_chains(entry);
+    crate::serial_println!("[HEAPDBG] Freed again by:");
+    print_chain(freed_by);
+}
+
+fn report_invalid_free(object: *mut u8, layout: Layout, freed_by: &[u64; CALLERS]) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    crate::serial_println!("[HEAPDBG] ==================================================");
+    crate::serial_println!("[HEAPDBG] BUG: invalid free of {:#x} ({} bytes): not a live heap object or header overwritten",
+        object as u64, layout.size());
+    crate::serial_println!("[HEAPDBG] Freed by:");
+    print_chain(freed_by);
+}
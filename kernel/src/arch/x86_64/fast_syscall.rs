@@ -84,6 +84,11 @@ pub fn init() {
     }
 }
 
+/// Where SYSCALL enters the kernel
+pub fn entry_address() -> u64 {
+    syscall_entry as usize as u64
+}
+
 // Fast syscall entry point - called by SYSCALL instruction
 #[naked]
 unsafe extern "C" fn syscall_entry() {
@@ -0,0 +1,488 @@
+//! The host controller interface
+//!
+//! Packets as transports carry them, each behind its H4 type byte;
+//! commands and their parameters, and the events the stack reads. A
+//! controller takes only as many commands and ACL packets as it has given
+//! credits for, so both wait in a queue here until it has room.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BluetoothAddress, BluetoothError};
+
+// Packet types, as H4 numbers them
+pub const PACKET_COMMAND: u8 = 0x01;
+pub const PACKET_ACL: u8 = 0x02;
+pub const PACKET_SCO: u8 = 0x03;
+pub const PACKET_EVENT: u8 = 0x04;
+
+// Commands, as OGF << 10 | OCF
+pub const INQUIRY: u16 = 0x0401;
+pub const INQUIRY_CANCEL: u16 = 0x0402;
+pub const CREATE_CONNECTION: u16 = 0x0405;
+pub const DISCONNECT: u16 = 0x0406;
+pub const ACCEPT_CONNECTION_REQUEST: u16 = 0x0409;
+pub const REJECT_CONNECTION_REQUEST: u16 = 0x040A;
+pub const LINK_KEY_REQUEST_REPLY: u16 = 0x040B;
+pub const LINK_KEY_REQUEST_NEGATIVE_REPLY: u16 = 0x040C;
+pub const PIN_CODE_REQUEST_REPLY: u16 = 0x040D;
+pub const PIN_CODE_REQUEST_NEGATIVE_REPLY: u16 = 0x040E;
+pub const AUTHENTICATION_REQUESTED: u16 = 0x0411;
+pub const SET_CONNECTION_ENCRYPTION: u16 = 0x0413;
+pub const REMOTE_NAME_REQUEST: u16 = 0x0419;
+pub const IO_CAPABILITY_REQUEST_REPLY: u16 = 0x042B;
+pub const USER_CONFIRMATION_REQUEST_REPLY: u16 = 0x042C;
+pub const USER_CONFIRMATION_REQUEST_NEGATIVE_REPLY: u16 = 0x042D;
+pub const USER_PASSKEY_REQUEST_NEGATIVE_REPLY: u16 = 0x042F;
+pub const IO_CAPABILITY_REQUEST_NEGATIVE_REPLY: u16 = 0x0434;
+pub const SET_EVENT_MASK: u16 = 0x0C01;
+pub const RESET: u16 = 0x0C03;
+pub const WRITE_LOCAL_NAME: u16 = 0x0C13;
+pub const WRITE_SCAN_ENABLE: u16 = 0x0C1A;
+pub const WRITE_CLASS_OF_DEVICE: u16 = 0x0C24;
+pub const WRITE_INQUIRY_MODE: u16 = 0x0C45;
+pub const WRITE_SIMPLE_PAIRING_MODE: u16 = 0x0C56;
+pub const READ_LOCAL_VERSION: u16 = 0x1001;
+pub const READ_BUFFER_SIZE: u16 = 0x1005;
+pub const READ_BD_ADDR: u16 = 0x1009;
+
+// Events
+const INQUIRY_COMPLETE: u8 = 0x01;
+const INQUIRY_RESULT: u8 = 0x02;
+const CONNECTION_COMPLETE: u8 = 0x03;
+const CONNECTION_REQUEST: u8 = 0x04;
+const DISCONNECTION_COMPLETE: u8 = 0x05;
+const AUTHENTICATION_COMPLETE: u8 = 0x06;
+const REMOTE_NAME_REQUEST_COMPLETE: u8 = 0x07;
+const ENCRYPTION_CHANGE: u8 = 0x08;
+const COMMAND_COMPLETE: u8 = 0x0E;
+const COMMAND_STATUS: u8 = 0x0F;
+const NUMBER_OF_COMPLETED_PACKETS: u8 = 0x13;
+const PIN_CODE_REQUEST: u8 = 0x16;
+const LINK_KEY_REQUEST: u8 = 0x17;
+const LINK_KEY_NOTIFICATION: u8 = 0x18;
+const INQUIRY_RESULT_WITH_RSSI: u8 = 0x22;
+const EXTENDED_INQUIRY_RESULT: u8 = 0x2F;
+const IO_CAPABILITY_REQUEST: u8 = 0x31;
+const IO_CAPABILITY_RESPONSE: u8 = 0x32;
+const USER_CONFIRMATION_REQUEST: u8 = 0x33;
+const USER_PASSKEY_REQUEST: u8 = 0x34;
+const SIMPLE_PAIRING_COMPLETE: u8 = 0x36;
+const USER_PASSKEY_NOTIFICATION: u8 = 0x3B;
+
+// Extended inquiry response fields that carry the name
+const EIR_NAME_SHORT: u8 = 0x08;
+const EIR_NAME_COMPLETE: u8 = 0x09;
+
+/// Moves packets to and from a controller. A packet starts with its H4
+/// type byte either way; `receive` returns one whole packet, or 0 when
+/// none has come in, and never waits for one.
+pub trait HciTransport: Send {
+    fn send(&mut self, data: &[u8]) -> Result<(), BluetoothError>;
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, BluetoothError>;
+}
+
+/// A device an inquiry found
+#[derive(Debug, Clone)]
+pub struct InquiryResult {
+    pub address: BluetoothAddress,
+    pub page_scan_mode: u8,
+    pub class: u32,
+    pub clock_offset: u16,
+    pub rssi: Option<i8>,
+    pub name: Option<String>,
+}
+
+/// The events the stack acts on
+#[derive(Debug, Clone)]
+pub enum Event {
+    InquiryComplete {
+        status: u8,
+    },
+    InquiryResult(Vec<InquiryResult>),
+    ConnectionComplete {
+        status: u8,
+        handle: u16,
+        address: BluetoothAddress,
+        link_type: u8,
+    },
+    ConnectionRequest {
+        address: BluetoothAddress,
+        class: u32,
+        link_type: u8,
+    },
+    DisconnectionComplete {
+        status: u8,
+        handle: u16,
+        reason: u8,
+    },
+    AuthenticationComplete {
+        status: u8,
+        handle: u16,
+    },
+    RemoteNameComplete {
+        status: u8,
+        address: BluetoothAddress,
+        name: String,
+    },
+    EncryptionChange {
+        status: u8,
+        handle: u16,
+        enabled: bool,
+    },
+    CommandComplete {
+        credits: u8,
+        opcode: u16,
+        status: u8,
+        parameters: Vec<u8>,
+    },
+    CommandStatus {
+        status: u8,
+        credits: u8,
+        opcode: u16,
+    },
+    /// ACL packets sent that the controller is done with, by handle
+    CompletedPackets(Vec<(u16, u16)>),
+    PinCodeRequest {
+        address: BluetoothAddress,
+    },
+    LinkKeyRequest {
+        address: BluetoothAddress,
+    },
+    LinkKeyNotification {
+        address: BluetoothAddress,
+        key: [u8; 16],
+        key_type: u8,
+    },
+    IoCapabilityRequest {
+        address: BluetoothAddress,
+    },
+    IoCapabilityResponse {
+        address: BluetoothAddress,
+        capability: u8,
+        authentication: u8,
+    },
+    UserConfirmationRequest {
+        address: BluetoothAddress,
+        value: u32,
+    },
+    UserPasskeyRequest {
+        address: BluetoothAddress,
+    },
+    UserPasskeyNotification {
+        address: BluetoothAddress,
+        passkey: u32,
+    },
+    SimplePairingComplete {
+        status: u8,
+        address: BluetoothAddress,
+    },
+    Other(u8),
+}
+
+fn address(data: &[u8]) -> BluetoothAddress {
+    let mut bytes = [0u8; 6];
+    bytes.copy_from_slice(&data[..6]);
+    BluetoothAddress::from_wire(bytes)
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u24_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+// A name field, up to its first NUL
+fn name(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// The name in an extended inquiry response, the complete one if it has both
+fn eir_name(mut data: &[u8]) -> Option<String> {
+    let mut short = None;
+    while let [length, rest @ ..] = data {
+        let length = *length as usize;
+        if length == 0 || rest.len() < length {
+            break;
+        }
+        match rest[0] {
+            EIR_NAME_COMPLETE => return Some(name(&rest[1..length])),
+            EIR_NAME_SHORT => short = Some(name(&rest[1..length])),
+            _ => {}
+        }
+        data = &rest[length..];
+    }
+    short
+}
+
+impl Event {
+    /// An event packet, less its H4 type byte
+    pub fn parse(packet: &[u8]) -> Option<Event> {
+        let [code, length, data @ ..] = packet else {
+            return None;
+        };
+        let data = data.get(..*length as usize)?;
+        // Every event's fixed fields must be there before they are read
+        let need = |size: usize| (data.len() >= size).then_some(());
+        let event = match *code {
+            INQUIRY_COMPLETE => {
+                need(1)?;
+                Event::InquiryComplete { status: data[0] }
+            }
+            INQUIRY_RESULT => {
+                let count = *data.first()? as usize;
+                need(1 + count * 14)?;
+                // The fields come as arrays, each device's at its index
+                let results = (0..count)
+                    .map(|i| InquiryResult {
+                        address: address(&data[1 + i * 6..]),
+                        page_scan_mode: data[1 + count * 6 + i],
+                        class: u24_at(data, 1 + count * 9 + i * 3),
+                        clock_offset: u16_at(data, 1 + count * 12 + i * 2),
+                        rssi: None,
+                        name: None,
+                    })
+                    .collect();
+                Event::InquiryResult(results)
+            }
+            INQUIRY_RESULT_WITH_RSSI => {
+                let count = *data.first()? as usize;
+                need(1 + count * 14)?;
+                let results = (0..count)
+                    .map(|i| InquiryResult {
+                        address: address(&data[1 + i * 6..]),
+                        page_scan_mode: data[1 + count * 6 + i],
+                        class: u24_at(data, 1 + count * 8 + i * 3),
+                        clock_offset: u16_at(data, 1 + count * 11 + i * 2),
+                        rssi: Some(data[1 + count * 13 + i] as i8),
+                        name: None,
+                    })
+                    .collect();
+                Event::InquiryResult(results)
+            }
+            EXTENDED_INQUIRY_RESULT => {
+                need(15)?;
+                Event::InquiryResult(vec![InquiryResult {
+                    address: address(&data[1..]),
+                    page_scan_mode: data[7],
+                    class: u24_at(data, 9),
+                    clock_offset: u16_at(data, 12),
+                    rssi: Some(data[14] as i8),
+                    name: eir_name(&data[15..]),
+                }])
+            }
+            CONNECTION_COMPLETE => {
+                need(10)?;
+                Event::ConnectionComplete {
+                    status: data[0],
+                    handle: u16_at(data, 1) & 0x0FFF,
+                    address: address(&data[3..]),
+                    link_type: data[9],
+                }
+            }
+            CONNECTION_REQUEST => {
+                need(10)?;
+                Event::ConnectionRequest { address: address(data), class: u24_at(data, 6), link_type: data[9] }
+            }
+            DISCONNECTION_COMPLETE => {
+                need(4)?;
+                Event::DisconnectionComplete { status: data[0], handle: u16_at(data, 1) & 0x0FFF, reason: data[3] }
+            }
+            AUTHENTICATION_COMPLETE => {
+                need(3)?;
+                Event::AuthenticationComplete { status: data[0], handle: u16_at(data, 1) & 0x0FFF }
+            }
+            REMOTE_NAME_REQUEST_COMPLETE => {
+                need(7)?;
+                Event::RemoteNameComplete { status: data[0], address: address(&data[1..]), name: name(&data[7..]) }
+            }
+            ENCRYPTION_CHANGE => {
+                need(4)?;
+                Event::EncryptionChange { status: data[0], handle: u16_at(data, 1) & 0x0FFF, enabled: data[3] != 0 }
+            }
+            COMMAND_COMPLETE => {
+                need(3)?;
+                Event::CommandComplete {
+                    credits: data[0],
+                    opcode: u16_at(data, 1),
+                    // Every command this stack sends returns a status first
+                    status: data.get(3).copied().unwrap_or(0),
+                    parameters: data.get(4..).unwrap_or(&[]).to_vec(),
+                }
+            }
+            COMMAND_STATUS => {
+                need(4)?;
+                Event::CommandStatus { status: data[0], credits: data[1], opcode: u16_at(data, 2) }
+            }
+            NUMBER_OF_COMPLETED_PACKETS => {
+                let count = *data.first()? as usize;
+                need(1 + count * 4)?;
+                let completed =
+                    (0..count).map(|i| (u16_at(data, 1 + i * 4) & 0x0FFF, u16_at(data, 3 + i * 4))).collect();
+                Event::CompletedPackets(completed)
+            }
+            PIN_CODE_REQUEST => {
+                need(6)?;
+                Event::PinCodeRequest { address: address(data) }
+            }
+            LINK_KEY_REQUEST => {
+                need(6)?;
+                Event::LinkKeyRequest { address: address(data) }
+            }
+            LINK_KEY_NOTIFICATION => {
+                need(23)?;
+                let mut key = [0u8; 16];
+                key.copy_from_slice(&data[6..22]);
+                Event::LinkKeyNotification { address: address(data), key, key_type: data[22] }
+            }
+            IO_CAPABILITY_REQUEST => {
+                need(6)?;
+                Event::IoCapabilityRequest { address: address(data) }
+            }
+            IO_CAPABILITY_RESPONSE => {
+                need(9)?;
+                Event::IoCapabilityResponse { address: address(data), capability: data[6], authentication: data[8] }
+            }
+            USER_CONFIRMATION_REQUEST => {
+                need(10)?;
+                Event::UserConfirmationRequest { address: address(data), value: u32_at(data, 6) }
+            }
+            USER_PASSKEY_REQUEST => {
+                need(6)?;
+                Event::UserPasskeyRequest { address: address(data) }
+            }
+            USER_PASSKEY_NOTIFICATION => {
+                need(10)?;
+                Event::UserPasskeyNotification { address: address(&data[..6]), passkey: u32_at(data, 6) }
+            }
+            SIMPLE_PAIRING_COMPLETE => {
+                need(7)?;
+                Event::SimplePairingComplete { status: data[0], address: address(&data[1..]) }
+            }
+            code => Event::Other(code),
+        };
+        Some(event)
+    }
+}
+
+/// A command packet, with its H4 type byte
+pub fn command(opcode: u16, parameters: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4 + parameters.len());
+    packet.push(PACKET_COMMAND);
+    packet.extend_from_slice(&opcode.to_le_bytes());
+    packet.push(parameters.len() as u8);
+    packet.extend_from_slice(parameters);
+    packet
+}
+
+/// What a controller has room for, and what waits until it does
+pub struct Controller {
+    /// Commands it will take now
+    command_credits: u8,
+    commands: VecDeque<Vec<u8>>,
+    /// The largest ACL payload it takes, and how many packets it has room
+    /// for; both 0 until its buffer size is read
+    pub acl_mtu: usize,
+    acl_credits: u16,
+    acl: VecDeque<Vec<u8>>,
+    /// ACL packets sent on each link and not yet completed, by handle
+    in_flight: BTreeMap<u16, u16>,
+}
+
+impl Controller {
+    pub const fn new() -> Self {
+        Self {
+            command_credits: 1,
+            commands: VecDeque::new(),
+            acl_mtu: 0,
+            acl_credits: 0,
+            acl: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn queue_command(&mut self, opcode: u16, parameters: &[u8]) {
+        self.commands.push_back(command(opcode, parameters));
+    }
+
+    /// An L2CAP frame for a link, in as many ACL packets as it takes
+    pub fn queue_acl(&mut self, handle: u16, frame: &[u8]) {
+        let mtu = self.acl_mtu.max(1);
+        for (i, chunk) in frame.chunks(mtu).enumerate() {
+            // Packet boundary: the first fragment of a flushable frame,
+            // then continuations
+            let flags: u16 = if i == 0 { 0x2000 } else { 0x1000 };
+            let mut packet = Vec::with_capacity(5 + chunk.len());
+            packet.push(PACKET_ACL);
+            packet.extend_from_slice(&(handle | flags).to_le_bytes());
+            packet.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            packet.extend_from_slice(chunk);
+            self.acl.push_back(packet);
+        }
+    }
+
+    /// Credits from a Command Complete or Command Status event
+    pub fn command_credits(&mut self, credits: u8) {
+        self.command_credits = credits;
+    }
+
+    /// The ACL buffers Read Buffer Size reported
+    pub fn set_buffers(&mut self, mtu: usize, packets: u16) {
+        self.acl_mtu = mtu;
+        self.acl_credits = packets;
+    }
+
+    pub fn completed(&mut self, handle: u16, packets: u16) {
+        if let Some(count) = self.in_flight.get_mut(&handle) {
+            let packets = packets.min(*count);
+            *count -= packets;
+            self.acl_credits += packets;
+        }
+    }
+
+    /// A link is gone: the controller has dropped its packets, and their
+    /// credits are back
+    pub fn link_closed(&mut self, handle: u16) {
+        if let Some(count) = self.in_flight.remove(&handle) {
+            self.acl_credits += count;
+        }
+        self.acl.retain(|packet| u16_at(packet, 1) & 0x0FFF != handle);
+    }
+
+    /// Everything waiting to be sent, for a reset
+    pub fn clear(&mut self) {
+        self.commands.clear();
+        self.acl.clear();
+        self.in_flight.clear();
+        self.command_credits = 1;
+    }
+
+    /// Send what the controller has room for
+    pub fn flush(&mut self, transport: &mut dyn HciTransport) -> Result<(), BluetoothError> {
+        while self.command_credits > 0 {
+            let Some(packet) = self.commands.pop_front() else {
+                break;
+            };
+            self.command_credits -= 1;
+            transport.send(&packet)?;
+        }
+        while self.acl_credits > 0 && self.acl_mtu > 0 {
+            let Some(packet) = self.acl.pop_front() else {
+                break;
+            };
+            self.acl_credits -= 1;
+            *self.in_flight.entry(u16_at(&packet, 1) & 0x0FFF).or_insert(0) += 1;
+            transport.send(&packet)?;
+        }
+        Ok(())
+    }
+}
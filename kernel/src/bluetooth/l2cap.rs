@@ -0,0 +1,462 @@
+//! L2CAP
+//!
+//! Basic mode channels over a link's ACL packets. Frames are put back
+//! together from the packets they came in, and split into as many as the
+//! controller takes on the way out. The signalling channel opens,
+//! configures and closes channels. Each side's configuration is accepted
+//! as asked, and the MTU is the only option either side reads. A channel a
+//! device opens before its link is encrypted is answered as pending until
+//! it is.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::hci::Controller;
+use super::profiles::{self, Notice};
+use super::{BluetoothAddress, BluetoothError, LinkId};
+
+const CID_SIGNALLING: u16 = 0x0001;
+const FIRST_DYNAMIC_CID: u16 = 0x0040;
+/// The MTU every channel has until configured otherwise
+const DEFAULT_MTU: u16 = 672;
+
+// Signalling commands
+const COMMAND_REJECT: u8 = 0x01;
+const CONNECTION_REQUEST: u8 = 0x02;
+const CONNECTION_RESPONSE: u8 = 0x03;
+const CONFIGURE_REQUEST: u8 = 0x04;
+const CONFIGURE_RESPONSE: u8 = 0x05;
+const DISCONNECTION_REQUEST: u8 = 0x06;
+const DISCONNECTION_RESPONSE: u8 = 0x07;
+const ECHO_REQUEST: u8 = 0x08;
+const ECHO_RESPONSE: u8 = 0x09;
+const INFORMATION_REQUEST: u8 = 0x0A;
+const INFORMATION_RESPONSE: u8 = 0x0B;
+
+// Connection results, and the status sent with a pending one
+const RESULT_SUCCESS: u16 = 0x0000;
+const RESULT_PENDING: u16 = 0x0001;
+const RESULT_PSM_NOT_SUPPORTED: u16 = 0x0002;
+const STATUS_AUTHENTICATION_PENDING: u16 = 0x0001;
+
+const OPTION_MTU: u8 = 0x01;
+// A configure request with more to follow
+const FLAG_CONTINUATION: u16 = 0x0001;
+
+// Information types, and the answer to the ones not known
+const INFO_EXTENDED_FEATURES: u16 = 0x0002;
+const INFO_FIXED_CHANNELS: u16 = 0x0003;
+const INFO_NOT_SUPPORTED: u16 = 0x0001;
+
+/// A channel, by the link it is on and its CID on this side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChannelId {
+    pub link: LinkId,
+    pub cid: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Asked for here, and not yet answered
+    WaitConnect,
+    /// Asked for by the device, and held until the link is encrypted; the
+    /// identifier is its request's
+    WaitSecurity(u8),
+    Config,
+    Open,
+    WaitDisconnect,
+}
+
+struct Channel {
+    psm: u16,
+    /// The CID on the device's side
+    remote: u16,
+    state: State,
+    /// Whether our configuration was accepted, and theirs
+    configured_out: bool,
+    configured_in: bool,
+    remote_mtu: u16,
+}
+
+/// A link's channels, and the frame coming in on it
+pub(crate) struct Channels {
+    channels: BTreeMap<u16, Channel>,
+    rx: Vec<u8>,
+    next_cid: u16,
+    next_ident: u8,
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+// A C-frame's fields, as little-endian halves
+fn fields(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+impl Channels {
+    pub(crate) const fn new() -> Self {
+        Self { channels: BTreeMap::new(), rx: Vec::new(), next_cid: FIRST_DYNAMIC_CID, next_ident: 1 }
+    }
+
+    fn allocate_cid(&mut self) -> u16 {
+        while self.channels.contains_key(&self.next_cid) {
+            self.next_cid = self.next_cid.checked_add(1).unwrap_or(FIRST_DYNAMIC_CID);
+        }
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.checked_add(1).unwrap_or(FIRST_DYNAMIC_CID);
+        cid
+    }
+
+    fn ident(&mut self) -> u8 {
+        let ident = self.next_ident;
+        // Identifier 0 is never used
+        self.next_ident = self.next_ident.checked_add(1).unwrap_or(1);
+        ident
+    }
+
+    fn frame(controller: &mut Controller, link: LinkId, cid: u16, payload: &[u8]) {
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&cid.to_le_bytes());
+        frame.extend_from_slice(payload);
+        controller.queue_acl(link.handle, &frame);
+    }
+
+    fn signal(controller: &mut Controller, link: LinkId, code: u8, ident: u8, data: &[u8]) {
+        let mut command = Vec::with_capacity(4 + data.len());
+        command.extend_from_slice(&[code, ident]);
+        command.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        command.extend_from_slice(data);
+        Self::frame(controller, link, CID_SIGNALLING, &command);
+    }
+
+    fn request_config(&mut self, controller: &mut Controller, link: LinkId, cid: u16) {
+        let Some(remote) = self.channels.get(&cid).map(|channel| channel.remote) else {
+            return;
+        };
+        let ident = self.ident();
+        let mut data = fields(&[remote, 0]);
+        data.extend_from_slice(&[OPTION_MTU, 2]);
+        data.extend_from_slice(&DEFAULT_MTU.to_le_bytes());
+        Self::signal(controller, link, CONFIGURE_REQUEST, ident, &data);
+    }
+
+    // Open once both sides' configurations are in
+    fn check_open(&mut self, link: LinkId, address: BluetoothAddress, cid: u16, notices: &mut Vec<Notice>) {
+        let Some(channel) = self.channels.get_mut(&cid) else {
+            return;
+        };
+        if channel.state == State::Config && channel.configured_in && channel.configured_out {
+            channel.state = State::Open;
+            notices.push(Notice::Opened { channel: ChannelId { link, cid }, psm: channel.psm, address });
+        }
+    }
+
+    fn close(&mut self, link: LinkId, cid: u16, notices: &mut Vec<Notice>) {
+        if let Some(channel) = self.channels.remove(&cid) {
+            notices.push(Notice::Closed { channel: ChannelId { link, cid }, psm: channel.psm });
+        }
+    }
+
+    /// An ACL packet's payload, with the packet boundary flags from its
+    /// header
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn receive(
+        &mut self,
+        controller: &mut Controller,
+        link: LinkId,
+        address: BluetoothAddress,
+        ready: bool,
+        boundary: u8,
+        payload: &[u8],
+        notices: &mut Vec<Notice>,
+    ) {
+        // 0b01 continues a frame; the others start one
+        if boundary == 0b01 {
+            if self.rx.is_empty() {
+                return;
+            }
+        } else {
+            self.rx.clear();
+        }
+        self.rx.extend_from_slice(payload);
+        if self.rx.len() < 4 {
+            return;
+        }
+        let length = u16_at(&self.rx, 0) as usize;
+        if self.rx.len() < 4 + length {
+            return;
+        }
+        let frame = core::mem::take(&mut self.rx);
+        let cid = u16_at(&frame, 2);
+        let data = &frame[4..4 + length];
+        if cid == CID_SIGNALLING {
+            self.signalling(controller, link, address, ready, data, notices);
+        } else if let Some(channel) = self.channels.get(&cid).filter(|channel| channel.state == State::Open) {
+            notices.push(Notice::Data { channel: ChannelId { link, cid }, psm: channel.psm, data: data.to_vec() });
+        }
+    }
+
+    fn signalling(
+        &mut self,
+        controller: &mut Controller,
+        link: LinkId,
+        address: BluetoothAddress,
+        ready: bool,
+        mut data: &[u8],
+        notices: &mut Vec<Notice>,
+    ) {
+        // A frame may carry several commands
+        while data.len() >= 4 {
+            let (code, ident, length) = (data[0], data[1], u16_at(data, 2) as usize);
+            let Some(command) = data.get(4..4 + length) else {
+                break;
+            };
+            data = &data[4 + length..];
+            self.command(controller, link, address, ready, code, ident, command, notices);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn command(
+        &mut self,
+        controller: &mut Controller,
+        link: LinkId,
+        address: BluetoothAddress,
+        ready: bool,
+        code: u8,
+        ident: u8,
+        data: &[u8],
+        notices: &mut Vec<Notice>,
+    ) {
+        let need = |size: usize| data.len() >= size;
+        match code {
+            CONNECTION_REQUEST if need(4) => {
+                let (psm, remote) = (u16_at(data, 0), u16_at(data, 2));
+                if !profiles::serves(psm) {
+                    let response = fields(&[0, remote, RESULT_PSM_NOT_SUPPORTED, 0]);
+                    Self::signal(controller, link, CONNECTION_RESPONSE, ident, &response);
+                    return;
+                }
+                let cid = self.allocate_cid();
+                let state = if ready { State::Config } else { State::WaitSecurity(ident) };
+                let channel = Channel {
+                    psm,
+                    remote,
+                    state,
+                    configured_out: false,
+                    configured_in: false,
+                    remote_mtu: DEFAULT_MTU,
+                };
+                self.channels.insert(cid, channel);
+                if ready {
+                    let response = fields(&[cid, remote, RESULT_SUCCESS, 0]);
+                    Self::signal(controller, link, CONNECTION_RESPONSE, ident, &response);
+                    self.request_config(controller, link, cid);
+                } else {
+                    let response = fields(&[cid, remote, RESULT_PENDING, STATUS_AUTHENTICATION_PENDING]);
+                    Self::signal(controller, link, CONNECTION_RESPONSE, ident, &response);
+                }
+            }
+            CONNECTION_RESPONSE if need(8) => {
+                let (remote, cid, result) = (u16_at(data, 0), u16_at(data, 2), u16_at(data, 4));
+                let Some(channel) = self.channels.get_mut(&cid).filter(|channel| channel.state == State::WaitConnect)
+                else {
+                    return;
+                };
+                match result {
+                    RESULT_SUCCESS => {
+                        channel.remote = remote;
+                        channel.state = State::Config;
+                        self.request_config(controller, link, cid);
+                    }
+                    RESULT_PENDING => {}
+                    _ => self.close(link, cid, notices),
+                }
+            }
+            CONFIGURE_REQUEST if need(4) => {
+                let (cid, flags) = (u16_at(data, 0), u16_at(data, 2));
+                let Some(channel) = self.channels.get_mut(&cid) else {
+                    let reject = fields(&[0x0002, cid, 0]);
+                    Self::signal(controller, link, COMMAND_REJECT, ident, &reject);
+                    return;
+                };
+                let mut options = &data[4..];
+                while let [kind, length, rest @ ..] = options {
+                    let length = *length as usize;
+                    if rest.len() < length {
+                        break;
+                    }
+                    // The top bit marks options that may be skipped
+                    if kind & 0x7F == OPTION_MTU && length == 2 {
+                        channel.remote_mtu = u16_at(rest, 0);
+                    }
+                    options = &rest[length..];
+                }
+                let response = fields(&[channel.remote, flags & FLAG_CONTINUATION, RESULT_SUCCESS]);
+                Self::signal(controller, link, CONFIGURE_RESPONSE, ident, &response);
+                if flags & FLAG_CONTINUATION == 0 {
+                    channel.configured_in = true;
+                    self.check_open(link, address, cid, notices);
+                }
+            }
+            CONFIGURE_RESPONSE if need(6) => {
+                let (cid, flags, result) = (u16_at(data, 0), u16_at(data, 2), u16_at(data, 4));
+                let Some(channel) = self.channels.get_mut(&cid) else {
+                    return;
+                };
+                if result != RESULT_SUCCESS {
+                    // Nothing else is offered, so the channel goes
+                    self.disconnect(controller, link, cid, notices);
+                } else if flags & FLAG_CONTINUATION == 0 {
+                    channel.configured_out = true;
+                    self.check_open(link, address, cid, notices);
+                }
+            }
+            DISCONNECTION_REQUEST if need(4) => {
+                let (cid, remote) = (u16_at(data, 0), u16_at(data, 2));
+                Self::signal(controller, link, DISCONNECTION_RESPONSE, ident, &data[..4]);
+                if self.channels.get(&cid).is_some_and(|channel| channel.remote == remote) {
+                    self.close(link, cid, notices);
+                }
+            }
+            DISCONNECTION_RESPONSE if need(4) => {
+                let cid = u16_at(data, 2);
+                if self.channels.get(&cid).is_some_and(|channel| channel.state == State::WaitDisconnect) {
+                    self.close(link, cid, notices);
+                }
+            }
+            ECHO_REQUEST => Self::signal(controller, link, ECHO_RESPONSE, ident, data),
+            INFORMATION_REQUEST if need(2) => {
+                let kind = u16_at(data, 0);
+                let mut response = fields(&[kind]);
+                match kind {
+                    // No extended features, and only the signalling channel
+                    INFO_EXTENDED_FEATURES => {
+                        response.extend_from_slice(&fields(&[RESULT_SUCCESS]));
+                        response.extend_from_slice(&0u32.to_le_bytes());
+                    }
+                    INFO_FIXED_CHANNELS => {
+                        response.extend_from_slice(&fields(&[RESULT_SUCCESS]));
+                        response.extend_from_slice(&(1u64 << CID_SIGNALLING).to_le_bytes());
+                    }
+                    _ => response.extend_from_slice(&fields(&[INFO_NOT_SUPPORTED])),
+                }
+                Self::signal(controller, link, INFORMATION_RESPONSE, ident, &response);
+            }
+            // Answers to requests this side never makes, and rejects of
+            // ones it did, which a timeout would end no differently
+            COMMAND_REJECT | ECHO_RESPONSE | INFORMATION_RESPONSE => {}
+            _ => {
+                // Command not understood
+                Self::signal(controller, link, COMMAND_REJECT, ident, &fields(&[0x0000]));
+            }
+        }
+    }
+
+    /// Ask the device for a channel to a PSM
+    pub(crate) fn connect(&mut self, controller: &mut Controller, link: LinkId, psm: u16) -> ChannelId {
+        let cid = self.allocate_cid();
+        let channel = Channel {
+            psm,
+            remote: 0,
+            state: State::WaitConnect,
+            configured_out: false,
+            configured_in: false,
+            remote_mtu: DEFAULT_MTU,
+        };
+        self.channels.insert(cid, channel);
+        let ident = self.ident();
+        Self::signal(controller, link, CONNECTION_REQUEST, ident, &fields(&[psm, cid]));
+        ChannelId { link, cid }
+    }
+
+    pub(crate) fn send(
+        &mut self,
+        controller: &mut Controller,
+        link: LinkId,
+        cid: u16,
+        data: &[u8],
+    ) -> Result<(), BluetoothError> {
+        let channel = self.channels.get(&cid).ok_or(BluetoothError::NotReady)?;
+        if channel.state != State::Open {
+            return Err(BluetoothError::NotReady);
+        }
+        if data.len() > channel.remote_mtu as usize {
+            return Err(BluetoothError::InvalidParameter);
+        }
+        Self::frame(controller, link, channel.remote, data);
+        Ok(())
+    }
+
+    pub(crate) fn disconnect(
+        &mut self,
+        controller: &mut Controller,
+        link: LinkId,
+        cid: u16,
+        notices: &mut Vec<Notice>,
+    ) {
+        let Some(channel) = self.channels.get_mut(&cid) else {
+            return;
+        };
+        match channel.state {
+            // Not known to the device yet, or going already
+            State::WaitConnect => self.close(link, cid, notices),
+            State::WaitDisconnect => {}
+            _ => {
+                channel.state = State::WaitDisconnect;
+                let remote = channel.remote;
+                let ident = self.ident();
+                Self::signal(controller, link, DISCONNECTION_REQUEST, ident, &fields(&[remote, cid]));
+            }
+        }
+    }
+
+    /// The link is encrypted: answer the channels held for it
+    pub(crate) fn link_ready(&mut self, controller: &mut Controller, link: LinkId) {
+        let held: Vec<(u16, u8, u16)> = self
+            .channels
+            .iter()
+            .filter_map(|(&cid, channel)| match channel.state {
+                State::WaitSecurity(ident) => Some((cid, ident, channel.remote)),
+                _ => None,
+            })
+            .collect();
+        for (cid, ident, remote) in held {
+            if let Some(channel) = self.channels.get_mut(&cid) {
+                channel.state = State::Config;
+            }
+            let response = fields(&[cid, remote, RESULT_SUCCESS, 0]);
+            Self::signal(controller, link, CONNECTION_RESPONSE, ident, &response);
+            self.request_config(controller, link, cid);
+        }
+    }
+
+    /// The link is gone, and every channel on it
+    pub(crate) fn close_all(&mut self, link: LinkId, notices: &mut Vec<Notice>) {
+        let cids: Vec<u16> = self.channels.keys().copied().collect();
+        for cid in cids {
+            self.close(link, cid, notices);
+        }
+        self.rx.clear();
+    }
+}
+
+/// Open a channel to a PSM on a link; the profile that serves the PSM is
+/// told once it is open, or closed if the device refuses it
+pub fn connect(link: LinkId, psm: u16) -> Result<ChannelId, BluetoothError> {
+    super::with_link(link, |channels, controller, _| Ok(channels.connect(controller, link, psm)))
+}
+
+pub fn send(channel: ChannelId, data: &[u8]) -> Result<(), BluetoothError> {
+    super::with_link(channel.link, |channels, controller, _| channels.send(controller, channel.link, channel.cid, data))
+}
+
+pub fn disconnect(channel: ChannelId) -> Result<(), BluetoothError> {
+    super::with_link(channel.link, |channels, controller, notices| {
+        channels.disconnect(controller, channel.link, channel.cid, notices);
+        Ok(())
+    })
+}
@@ -1,37 +1,69 @@
-pub mod core;
+//! Bluetooth
+//!
+//! A host stack for BR/EDR controllers. Each controller is an adapter,
+//! named hci0, hci1 and on, and is reached through an `HciTransport`. The
+//! driver in `drivers::bluetooth::usb` binds USB dongles, and UART modules
+//! that speak H4 are attached by hand. An adapter page scans so paired
+//! devices can connect back, but is never discoverable.
+//!
+//! Everything runs from `poll`: the packets each transport has received
+//! are handled, and what waits to be sent goes out as the controller has
+//! room. A link is authenticated, pairing on the way if there is no link
+//! key yet, and then encrypted before its channels carry anything; see
+//! `security`. Profiles sit on L2CAP and take the channels on their PSMs;
+//! see `profiles`.
+
+pub mod hci;
+pub mod l2cap;
 pub mod profiles;
-pub mod ble;
 pub mod security;
 
-use alloc::collections::BTreeMap;
-use alloc::vec::Vec;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
 use alloc::string::String;
-use spin::RwLock;
-use core::sync::atomic::{AtomicU32, Ordering};
-
-pub use core::hci::{HciController, HciCommand, HciEvent};
-pub use core::l2cap::{L2capChannel, L2capPacket};
-pub use security::{PairingMode, SecurityLevel};
-pub use ble::{BleAdvertiser, BleScanner};
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BluetoothVersion {
-    V1_0,
-    V1_1,
-    V1_2,
-    V2_0,
-    V2_1,
-    V3_0,
-    V4_0,
-    V4_1,
-    V4_2,
-    V5_0,
-    V5_1,
-    V5_2,
-    V5_3,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use self::hci::{Controller, Event, HciTransport, InquiryResult};
+use self::l2cap::Channels;
+use self::profiles::Notice;
+use self::security::Bond;
+use crate::{println, serial_println};
+
+// The class of device adapters give: a desktop computer
+const CLASS_OF_DEVICE: u32 = 0x00_0104;
+// Every event but the LE meta event, as BlueZ asks for on 2.1 controllers
+const EVENT_MASK: u64 = 0x3DBF_F807_FFFB_FFFF;
+// Page scan on, inquiry scan off
+const SCAN_PAGE: u8 = 0x02;
+// Inquiry with RSSI and extended results
+const INQUIRY_MODE_EXTENDED: u8 = 0x02;
+// The general inquiry access code
+const GIAC: [u8; 3] = [0x33, 0x8B, 0x9E];
+// DM1, DH1, DM3, DH3, DM5 and DH5
+const ACL_PACKET_TYPES: u16 = 0xCC18;
+const LINK_TYPE_ACL: u8 = 0x01;
+const ROLE_MASTER: u8 = 0x00;
+// Disconnect and reject reasons
+const REASON_AUTHENTICATION_FAILURE: u8 = 0x05;
+const REASON_USER_ENDED: u8 = 0x13;
+const REASON_UNACCEPTABLE_ADDRESS: u8 = 0x0F;
+// The status Authentication Complete gives when the device has lost the key
+const STATUS_KEY_MISSING: u8 = 0x06;
+// The largest packet a transport hands over: an ACL packet of 4 KiB
+const MAX_PACKET: usize = 1 + 4 + 4096;
+// Packets each adapter handles at a time
+const POLL_BUDGET: usize = 32;
+
+/// A device address, most significant byte first as it is written; HCI
+/// sends it the other way round
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BluetoothAddress([u8; 6]);
 
 impl BluetoothAddress {
@@ -39,7 +71,38 @@ impl BluetoothAddress {
         Self(addr)
     }
 
-    pub fn from_str(s: &str) -> Result<Self, BluetoothError> {
+    pub fn from_wire(mut bytes: [u8; 6]) -> Self {
+        bytes.reverse();
+        Self(bytes)
+    }
+
+    pub fn to_wire(self) -> [u8; 6] {
+        let mut bytes = self.0;
+        bytes.reverse();
+        bytes
+    }
+
+    /// Twelve hex digits, as registry keys name devices
+    pub fn hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        if s.len() != 12 || !s.is_ascii() {
+            return None;
+        }
+        let mut addr = [0u8; 6];
+        for (i, byte) in addr.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(Self(addr))
+    }
+}
+
+impl FromStr for BluetoothAddress {
+    type Err = BluetoothError;
+
+    fn from_str(s: &str) -> Result<Self, BluetoothError> {
         let parts: Vec<&str> = s.split(':').collect();
         if parts.len() != 6 {
             return Err(BluetoothError::InvalidAddress);
@@ -47,50 +110,21 @@ impl BluetoothAddress {
 
         let mut addr = [0u8; 6];
         for (i, part) in parts.iter().enumerate() {
-            addr[i] = u8::from_str_radix(part, 16)
-                .map_err(|_| BluetoothError::InvalidAddress)?;
+            addr[i] = u8::from_str_radix(part, 16).map_err(|_| BluetoothError::InvalidAddress)?;
         }
 
         Ok(Self(addr))
     }
-
-    pub fn to_string(&self) -> String {
-        format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-                self.0[0], self.0[1], self.0[2],
-                self.0[3], self.0[4], self.0[5])
-    }
 }
 
-#[derive(Debug, Clone)]
-pub struct BluetoothDevice {
-    pub address: BluetoothAddress,
-    pub name: Option<String>,
-    pub class: u32,
-    pub rssi: Option<i8>,
-    pub paired: bool,
-    pub connected: bool,
-    pub trusted: bool,
-    pub blocked: bool,
-    pub profiles: Vec<BluetoothProfile>,
-    pub version: BluetoothVersion,
+impl fmt::Display for BluetoothAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d, e, g)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BluetoothProfile {
-    A2DP,
-    AVRCP,
-    HFP,
-    HSP,
-    HID,
-    PAN,
-    SPP,
-    OBEX,
-    MAP,
-    PBAP,
-    BLE,
-}
-
-#[derive(Debug)]
 pub enum BluetoothError {
     NotSupported,
     NotReady,
@@ -108,180 +142,735 @@ pub enum BluetoothError {
     ProtocolError,
 }
 
-pub struct BluetoothAdapter {
-    id: u32,
-    address: BluetoothAddress,
-    name: String,
-    powered: bool,
-    discoverable: bool,
-    discovering: bool,
-    devices: RwLock<BTreeMap<BluetoothAddress, BluetoothDevice>>,
-    controller: Option<HciController>,
+/// Where a device's link stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    Disconnected,
+    Connecting,
+    /// Authenticating, pairing if need be, then encrypting
+    Securing,
+    Connected,
+    Failed(BluetoothError),
+}
+
+/// A device an adapter knows of: found by an inquiry, paired, or connected
+#[derive(Debug, Clone)]
+pub struct BluetoothDevice {
+    pub address: BluetoothAddress,
+    pub name: Option<String>,
+    pub class: u32,
+    pub rssi: Option<i8>,
+    pub paired: bool,
+    pub status: LinkStatus,
+    page_scan_mode: u8,
+    clock_offset: Option<u16>,
+    /// Whether the link was asked for here
+    initiated: bool,
 }
 
-impl BluetoothAdapter {
-    pub fn new(id: u32, address: BluetoothAddress) -> Self {
+impl BluetoothDevice {
+    fn new(address: BluetoothAddress) -> Self {
         Self {
-            id,
             address,
-            name: format!("hci{}", id),
-            powered: false,
-            discoverable: false,
-            discovering: false,
-            devices: RwLock::new(BTreeMap::new()),
-            controller: None,
+            name: None,
+            class: 0,
+            rssi: None,
+            paired: false,
+            status: LinkStatus::Disconnected,
+            // R1, the mode most devices scan in
+            page_scan_mode: 0x01,
+            clock_offset: None,
+            initiated: false,
         }
     }
+}
+
+/// A link, by its adapter and connection handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LinkId {
+    pub adapter: usize,
+    pub handle: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkState {
+    Authenticating,
+    Encrypting,
+    Ready,
+}
+
+struct Link {
+    address: BluetoothAddress,
+    state: LinkState,
+    channels: Channels,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdapterState {
+    /// Being reset and set up
+    Starting,
+    Ready,
+    Failed,
+}
+
+struct Adapter {
+    name: String,
+    /// What the adapter is, as its transport says
+    description: String,
+    transport: Box<dyn HciTransport>,
+    controller: Controller,
+    state: AdapterState,
+    address: BluetoothAddress,
+    hci_version: u8,
+    inquiring: bool,
+    /// Devices the last inquiry found without a name, still to ask, and
+    /// the one being asked
+    unnamed: VecDeque<BluetoothAddress>,
+    naming: Option<BluetoothAddress>,
+    devices: BTreeMap<BluetoothAddress, BluetoothDevice>,
+    links: BTreeMap<u16, Link>,
+    /// PINs for legacy pairing, as `connect` was given them
+    pins: BTreeMap<BluetoothAddress, String>,
+    /// The IO capability of each device being paired
+    pairing: BTreeMap<BluetoothAddress, u8>,
+    /// Whether a failed receive was logged, so it is only once
+    receive_failed: bool,
+}
+
+/// An adapter, for listing
+#[derive(Debug, Clone)]
+pub struct AdapterInfo {
+    pub index: usize,
+    pub name: String,
+    pub description: String,
+    pub state: AdapterState,
+    pub address: BluetoothAddress,
+    pub hci_version: u8,
+    pub scanning: bool,
+    pub links: usize,
+}
+
+static ADAPTERS: Mutex<BTreeMap<usize, Adapter>> = Mutex::new(BTreeMap::new());
+static NEXT_ADAPTER: AtomicUsize = AtomicUsize::new(0);
+
+impl Adapter {
+    fn command(&mut self, opcode: u16, parameters: &[u8]) {
+        self.controller.queue_command(opcode, parameters);
+    }
 
-    pub fn power_on(&mut self) -> Result<(), BluetoothError> {
-        if let Some(ref mut controller) = self.controller {
-            controller.reset()?;
-            controller.set_event_mask(0xFFFFFFFFFFFFFFFF)?;
-            controller.set_local_name(&self.name)?;
-            self.powered = true;
-            Ok(())
-        } else {
-            Err(BluetoothError::NoAdapter)
+    fn flush(&mut self) {
+        if let Err(e) = self.controller.flush(self.transport.as_mut()) {
+            serial_println!("bluetooth: {}: cannot send: {:?}", self.name, e);
         }
     }
 
-    pub fn power_off(&mut self) -> Result<(), BluetoothError> {
-        self.powered = false;
-        if let Some(ref mut controller) = self.controller {
-            controller.reset()?;
+    fn device(&mut self, address: BluetoothAddress) -> &mut BluetoothDevice {
+        self.devices.entry(address).or_insert_with(|| BluetoothDevice::new(address))
+    }
+
+    fn device_name(&self, address: BluetoothAddress) -> String {
+        match self.devices.get(&address).and_then(|device| device.name.clone()) {
+            Some(name) => name,
+            None => format!("{}", address),
         }
-        Ok(())
     }
 
-    pub fn start_discovery(&mut self) -> Result<(), BluetoothError> {
-        if !self.powered {
-            return Err(BluetoothError::NotReady);
+    fn link_of(&self, address: BluetoothAddress) -> Option<u16> {
+        self.links.iter().find(|(_, link)| link.address == address).map(|(&handle, _)| handle)
+    }
+
+    /// Reset the controller and set it up; it is ready once page scan is on
+    fn start(&mut self) {
+        self.command(hci::RESET, &[]);
+        self.command(hci::READ_BD_ADDR, &[]);
+        self.command(hci::READ_BUFFER_SIZE, &[]);
+        self.command(hci::READ_LOCAL_VERSION, &[]);
+        self.command(hci::SET_EVENT_MASK, &EVENT_MASK.to_le_bytes());
+        self.command(hci::WRITE_SIMPLE_PAIRING_MODE, &[1]);
+        self.command(hci::WRITE_INQUIRY_MODE, &[INQUIRY_MODE_EXTENDED]);
+        self.command(hci::WRITE_CLASS_OF_DEVICE, &CLASS_OF_DEVICE.to_le_bytes()[..3]);
+        let mut name = [0u8; 248];
+        let hostname = crate::container::nsproxy::hostname(0);
+        let length = hostname.len().min(name.len() - 1);
+        name[..length].copy_from_slice(&hostname.as_bytes()[..length]);
+        self.command(hci::WRITE_LOCAL_NAME, &name);
+        self.command(hci::WRITE_SCAN_ENABLE, &[SCAN_PAGE]);
+        self.flush();
+    }
+
+    fn poll(&mut self, index: usize, notices: &mut Vec<Notice>) {
+        let mut buffer = vec![0u8; MAX_PACKET];
+        for _ in 0..POLL_BUDGET {
+            let length = match self.transport.receive(&mut buffer) {
+                Ok(0) => break,
+                Ok(length) => length,
+                Err(e) => {
+                    if !self.receive_failed {
+                        serial_println!("bluetooth: {}: cannot receive: {:?}", self.name, e);
+                        self.receive_failed = true;
+                    }
+                    break;
+                }
+            };
+            let packet = &buffer[..length];
+            match packet[0] {
+                hci::PACKET_EVENT => match Event::parse(&packet[1..]) {
+                    Some(event) => self.event(index, event, notices),
+                    None => serial_println!("bluetooth: {}: malformed event {:02x?}", self.name, &packet[1..]),
+                },
+                hci::PACKET_ACL => self.acl(index, &packet[1..], notices),
+                // SCO carries voice, which no profile takes yet
+                _ => {}
+            }
         }
+        self.flush();
+    }
 
-        if let Some(ref mut controller) = self.controller {
-            controller.inquiry(10, 10)?;
-            self.discovering = true;
-            Ok(())
-        } else {
-            Err(BluetoothError::NoAdapter)
+    fn acl(&mut self, index: usize, packet: &[u8], notices: &mut Vec<Notice>) {
+        if packet.len() < 4 {
+            return;
         }
+        let header = u16::from_le_bytes([packet[0], packet[1]]);
+        let length = u16::from_le_bytes([packet[2], packet[3]]) as usize;
+        let (handle, boundary) = (header & 0x0FFF, ((header >> 12) & 0x03) as u8);
+        let Some(payload) = packet.get(4..4 + length) else {
+            return;
+        };
+        let Some(link) = self.links.get_mut(&handle) else {
+            return;
+        };
+        let id = LinkId { adapter: index, handle };
+        let ready = link.state == LinkState::Ready;
+        link.channels.receive(&mut self.controller, id, link.address, ready, boundary, payload, notices);
     }
 
-    pub fn stop_discovery(&mut self) -> Result<(), BluetoothError> {
-        if let Some(ref mut controller) = self.controller {
-            controller.cancel_inquiry()?;
-            self.discovering = false;
-            Ok(())
-        } else {
-            Err(BluetoothError::NoAdapter)
+    fn event(&mut self, index: usize, event: Event, notices: &mut Vec<Notice>) {
+        match event {
+            Event::CommandComplete { credits, opcode, status, parameters } => {
+                self.controller.command_credits(credits);
+                if status != 0 {
+                    self.command_failed(opcode, status);
+                } else {
+                    self.command_complete(opcode, &parameters);
+                }
+            }
+            Event::CommandStatus { status, credits, opcode } => {
+                self.controller.command_credits(credits);
+                if status != 0 {
+                    self.command_failed(opcode, status);
+                }
+            }
+            Event::CompletedPackets(completed) => {
+                for (handle, packets) in completed {
+                    self.controller.completed(handle, packets);
+                }
+            }
+            Event::InquiryResult(results) => {
+                for result in results {
+                    self.found(result);
+                }
+            }
+            Event::InquiryComplete { .. } => {
+                self.inquiring = false;
+                self.next_name();
+            }
+            Event::RemoteNameComplete { status, address, name } => {
+                if status == 0 {
+                    self.device(address).name = Some(name);
+                }
+                if self.naming == Some(address) {
+                    self.naming = None;
+                }
+                self.next_name();
+            }
+            Event::ConnectionRequest { address, class, link_type } => {
+                // Only paired devices come back on their own, and the one
+                // being connected to may answer with its own request
+                let known = security::link_key(self.address, address).is_some()
+                    || self.devices.get(&address).is_some_and(|device| device.status == LinkStatus::Connecting);
+                if link_type == LINK_TYPE_ACL && known {
+                    let device = self.device(address);
+                    device.class = class;
+                    device.initiated &= device.status == LinkStatus::Connecting;
+                    device.status = LinkStatus::Connecting;
+                    let mut parameters = address.to_wire().to_vec();
+                    parameters.push(ROLE_MASTER);
+                    self.command(hci::ACCEPT_CONNECTION_REQUEST, &parameters);
+                } else {
+                    let mut parameters = address.to_wire().to_vec();
+                    parameters.push(REASON_UNACCEPTABLE_ADDRESS);
+                    self.command(hci::REJECT_CONNECTION_REQUEST, &parameters);
+                }
+            }
+            Event::ConnectionComplete { status, handle, address, link_type } => {
+                if link_type != LINK_TYPE_ACL {
+                    return;
+                }
+                if status != 0 {
+                    serial_println!("bluetooth: {}: cannot connect to {}: status {:#04x}", self.name, address, status);
+                    self.device(address).status = LinkStatus::Failed(BluetoothError::ConnectionFailed);
+                    return;
+                }
+                self.device(address).status = LinkStatus::Securing;
+                let link = Link { address, state: LinkState::Authenticating, channels: Channels::new() };
+                self.links.insert(handle, link);
+                self.command(hci::AUTHENTICATION_REQUESTED, &handle.to_le_bytes());
+            }
+            Event::AuthenticationComplete { status, handle } => {
+                let Some(link) = self.links.get_mut(&handle) else {
+                    return;
+                };
+                if status == 0 {
+                    link.state = LinkState::Encrypting;
+                    let mut parameters = handle.to_le_bytes().to_vec();
+                    parameters.push(1);
+                    self.command(hci::SET_CONNECTION_ENCRYPTION, &parameters);
+                    return;
+                }
+                let address = link.address;
+                // The device was reset or unpaired, so the key is no good
+                if status == STATUS_KEY_MISSING && security::forget(self.address, address) {
+                    self.device(address).paired = false;
+                }
+                serial_println!("bluetooth: {}: {} failed to authenticate: status {:#04x}", self.name, address, status);
+                self.fail(handle, BluetoothError::AuthenticationFailed);
+            }
+            Event::EncryptionChange { status, handle, enabled } => {
+                let Some(link) = self.links.get_mut(&handle) else {
+                    return;
+                };
+                if status != 0 || !enabled {
+                    serial_println!(
+                        "bluetooth: {}: {} is not encrypted: status {:#04x}",
+                        self.name,
+                        link.address,
+                        status
+                    );
+                    self.fail(handle, BluetoothError::AuthenticationFailed);
+                    return;
+                }
+                if link.state == LinkState::Ready {
+                    return;
+                }
+                link.state = LinkState::Ready;
+                let id = LinkId { adapter: index, handle };
+                link.channels.link_ready(&mut self.controller, id);
+                let address = link.address;
+                let device = self.device(address);
+                device.status = LinkStatus::Connected;
+                let (class, initiated) = (device.class, device.initiated);
+                notices.push(Notice::LinkReady { link: id, address, class, initiated });
+            }
+            Event::DisconnectionComplete { status, handle, reason } => {
+                if status != 0 {
+                    return;
+                }
+                let Some(mut link) = self.links.remove(&handle) else {
+                    return;
+                };
+                link.channels.close_all(LinkId { adapter: index, handle }, notices);
+                self.controller.link_closed(handle);
+                let device = self.device(link.address);
+                device.initiated = false;
+                if !matches!(device.status, LinkStatus::Failed(_)) {
+                    device.status = LinkStatus::Disconnected;
+                }
+                serial_println!("bluetooth: {}: {} disconnected: reason {:#04x}", self.name, link.address, reason);
+            }
+            Event::LinkKeyRequest { address } => {
+                let mut parameters = address.to_wire().to_vec();
+                match security::link_key(self.address, address) {
+                    Some(key) => {
+                        parameters.extend_from_slice(&key);
+                        self.command(hci::LINK_KEY_REQUEST_REPLY, &parameters);
+                    }
+                    None => self.command(hci::LINK_KEY_REQUEST_NEGATIVE_REPLY, &parameters),
+                }
+            }
+            Event::LinkKeyNotification { address, key, key_type } => {
+                let device = self.device(address);
+                device.paired = true;
+                let bond = Bond { address, key, key_type, name: device.name.clone(), class: device.class };
+                security::store(self.address, &bond);
+                serial_println!("bluetooth: {}: paired with {}", self.name, self.device_name(address));
+            }
+            Event::PinCodeRequest { address } => {
+                let mut parameters = address.to_wire().to_vec();
+                match self.pins.get(&address) {
+                    Some(pin) => {
+                        let mut code = [0u8; 16];
+                        let length = pin.len().min(code.len());
+                        code[..length].copy_from_slice(&pin.as_bytes()[..length]);
+                        parameters.push(length as u8);
+                        parameters.extend_from_slice(&code);
+                        self.command(hci::PIN_CODE_REQUEST_REPLY, &parameters);
+                    }
+                    None => {
+                        println!("bluetooth: {} needs a PIN; connect to it again with one", self.device_name(address));
+                        self.command(hci::PIN_CODE_REQUEST_NEGATIVE_REPLY, &parameters);
+                    }
+                }
+            }
+            Event::IoCapabilityRequest { address } => {
+                let mut parameters = address.to_wire().to_vec();
+                parameters.extend_from_slice(&[security::IO_CAPABILITY, 0, security::AUTHENTICATION]);
+                self.command(hci::IO_CAPABILITY_REQUEST_REPLY, &parameters);
+            }
+            Event::IoCapabilityResponse { address, capability, .. } => {
+                self.pairing.insert(address, capability);
+            }
+            Event::UserConfirmationRequest { address, value } => {
+                let capability = self.pairing.get(&address).copied().unwrap_or(security::NO_INPUT_NO_OUTPUT);
+                if security::shows_number(capability) {
+                    println!("bluetooth: confirm on {} that it shows {:06}", self.device_name(address), value);
+                }
+                self.command(hci::USER_CONFIRMATION_REQUEST_REPLY, &address.to_wire());
+            }
+            Event::UserPasskeyNotification { address, passkey } => {
+                println!("bluetooth: type {:06} on {}, then Enter", passkey, self.device_name(address));
+            }
+            Event::UserPasskeyRequest { address } => {
+                // This side shows passkeys, and has none to type in
+                self.command(hci::USER_PASSKEY_REQUEST_NEGATIVE_REPLY, &address.to_wire());
+            }
+            Event::SimplePairingComplete { status, address } => {
+                self.pairing.remove(&address);
+                if status != 0 {
+                    serial_println!(
+                        "bluetooth: {}: pairing with {} failed: status {:#04x}",
+                        self.name,
+                        address,
+                        status
+                    );
+                }
+            }
+            Event::Other(_) => {}
         }
     }
 
-    pub fn pair_device(&mut self, address: BluetoothAddress) -> Result<(), BluetoothError> {
-        if !self.powered {
-            return Err(BluetoothError::NotReady);
+    fn command_complete(&mut self, opcode: u16, parameters: &[u8]) {
+        match opcode {
+            hci::READ_BD_ADDR if parameters.len() >= 6 => {
+                let mut bytes = [0u8; 6];
+                bytes.copy_from_slice(&parameters[..6]);
+                self.address = BluetoothAddress::from_wire(bytes);
+                // The devices paired before, so they can come back
+                for bond in security::bonds(self.address) {
+                    let device = self.device(bond.address);
+                    device.paired = true;
+                    device.class = bond.class;
+                    if device.name.is_none() {
+                        device.name = bond.name;
+                    }
+                }
+            }
+            hci::READ_BUFFER_SIZE if parameters.len() >= 5 => {
+                let mtu = u16::from_le_bytes([parameters[0], parameters[1]]) as usize;
+                let packets = u16::from_le_bytes([parameters[3], parameters[4]]);
+                self.controller.set_buffers(mtu, packets);
+            }
+            hci::READ_LOCAL_VERSION if !parameters.is_empty() => self.hci_version = parameters[0],
+            hci::WRITE_SCAN_ENABLE if self.state == AdapterState::Starting => {
+                self.state = AdapterState::Ready;
+                serial_println!("bluetooth: {} is {}, on {}", self.name, self.address, self.description);
+            }
+            hci::INQUIRY_CANCEL => self.inquiring = false,
+            _ => {}
         }
+    }
 
-        // Implement pairing logic
-        Ok(())
+    fn command_failed(&mut self, opcode: u16, status: u8) {
+        match opcode {
+            hci::RESET => {
+                serial_println!("bluetooth: {}: reset failed: status {:#04x}", self.name, status);
+                self.state = AdapterState::Failed;
+                return;
+            }
+            hci::INQUIRY => self.inquiring = false,
+            hci::REMOTE_NAME_REQUEST => {
+                self.naming = None;
+                self.next_name();
+            }
+            hci::CREATE_CONNECTION => {
+                for device in self.devices.values_mut() {
+                    if device.status == LinkStatus::Connecting && device.initiated {
+                        device.status = LinkStatus::Failed(BluetoothError::ConnectionFailed);
+                        device.initiated = false;
+                    }
+                }
+            }
+            _ => {}
+        }
+        serial_println!("bluetooth: {}: command {:#06x} failed: status {:#04x}", self.name, opcode, status);
     }
 
-    pub fn connect_device(&mut self, address: BluetoothAddress) -> Result<(), BluetoothError> {
-        if !self.powered {
-            return Err(BluetoothError::NotReady);
+    // A link that could not be secured is dropped
+    fn fail(&mut self, handle: u16, error: BluetoothError) {
+        if let Some(address) = self.links.get(&handle).map(|link| link.address) {
+            self.device(address).status = LinkStatus::Failed(error);
         }
+        let mut parameters = handle.to_le_bytes().to_vec();
+        parameters.push(REASON_AUTHENTICATION_FAILURE);
+        self.command(hci::DISCONNECT, &parameters);
+    }
 
-        // Implement connection logic
-        Ok(())
+    fn found(&mut self, result: InquiryResult) {
+        let device = self.device(result.address);
+        device.class = result.class;
+        device.page_scan_mode = result.page_scan_mode;
+        device.clock_offset = Some(result.clock_offset);
+        if result.rssi.is_some() {
+            device.rssi = result.rssi;
+        }
+        if result.name.is_some() {
+            device.name = result.name;
+        } else if device.name.is_none() && !self.unnamed.contains(&result.address) {
+            self.unnamed.push_back(result.address);
+        }
     }
 
-    pub fn disconnect_device(&mut self, address: BluetoothAddress) -> Result<(), BluetoothError> {
-        // Implement disconnection logic
-        Ok(())
+    // Ask the next unnamed device its name, once the inquiry is over
+    fn next_name(&mut self) {
+        if self.inquiring || self.naming.is_some() {
+            return;
+        }
+        let Some(address) = self.unnamed.pop_front() else {
+            return;
+        };
+        let device = self.device(address);
+        let mut parameters = address.to_wire().to_vec();
+        parameters.extend_from_slice(&[device.page_scan_mode, 0]);
+        // Bit 15 says the clock offset is known
+        let clock_offset = device.clock_offset.map_or(0, |offset| offset | 0x8000);
+        parameters.extend_from_slice(&clock_offset.to_le_bytes());
+        self.naming = Some(address);
+        self.command(hci::REMOTE_NAME_REQUEST, &parameters);
     }
 
-    pub fn get_devices(&self) -> Vec<BluetoothDevice> {
-        self.devices.read().values().cloned().collect()
+    fn info(&self, index: usize) -> AdapterInfo {
+        AdapterInfo {
+            index,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            state: self.state,
+            address: self.address,
+            hci_version: self.hci_version,
+            scanning: self.inquiring || self.naming.is_some() || !self.unnamed.is_empty(),
+            links: self.links.len(),
+        }
     }
+}
 
-    pub fn get_device(&self, address: BluetoothAddress) -> Option<BluetoothDevice> {
-        self.devices.read().get(&address).cloned()
+// The adapter asked for, or the first one ready
+fn find(
+    adapters: &mut BTreeMap<usize, Adapter>,
+    index: Option<usize>,
+) -> Result<(usize, &mut Adapter), BluetoothError> {
+    let (index, adapter) = match index {
+        Some(index) => adapters.get_mut(&index).map(|adapter| (index, adapter)),
+        None => adapters.iter_mut().find(|(_, adapter)| adapter.state == AdapterState::Ready).map(|(&i, a)| (i, a)),
     }
+    .ok_or(BluetoothError::NoAdapter)?;
+    if adapter.state != AdapterState::Ready {
+        return Err(BluetoothError::NotReady);
+    }
+    Ok((index, adapter))
 }
 
-pub struct BluetoothManager {
-    adapters: RwLock<BTreeMap<u32, BluetoothAdapter>>,
-    next_adapter_id: AtomicU32,
+// Act on an adapter under the lock, then send what it queued and tell the
+// profiles what happened
+fn with_adapter<R>(
+    index: Option<usize>,
+    f: impl FnOnce(usize, &mut Adapter, &mut Vec<Notice>) -> Result<R, BluetoothError>,
+) -> Result<R, BluetoothError> {
+    let mut notices = Vec::new();
+    let result = {
+        let mut adapters = ADAPTERS.lock();
+        let (index, adapter) = find(&mut adapters, index)?;
+        let result = f(index, adapter, &mut notices);
+        adapter.flush();
+        result
+    };
+    profiles::dispatch(notices);
+    result
 }
 
-impl BluetoothManager {
-    pub const fn new() -> Self {
-        Self {
-            adapters: RwLock::new(BTreeMap::new()),
-            next_adapter_id: AtomicU32::new(0),
-        }
-    }
+/// Act on a link's channels; for `l2cap`
+pub(crate) fn with_link<R>(
+    link: LinkId,
+    f: impl FnOnce(&mut Channels, &mut Controller, &mut Vec<Notice>) -> Result<R, BluetoothError>,
+) -> Result<R, BluetoothError> {
+    with_adapter(Some(link.adapter), |_, adapter, notices| {
+        let channels = &mut adapter.links.get_mut(&link.handle).ok_or(BluetoothError::NotReady)?.channels;
+        f(channels, &mut adapter.controller, notices)
+    })
+}
 
-    pub fn register_adapter(&self, address: BluetoothAddress) -> u32 {
-        let id = self.next_adapter_id.fetch_add(1, Ordering::SeqCst);
-        let adapter = BluetoothAdapter::new(id, address);
-        self.adapters.write().insert(id, adapter);
-        id
-    }
+/// Take a controller on; it is reset and set up from `poll`
+pub fn add_adapter(transport: Box<dyn HciTransport>, description: String) -> usize {
+    let index = NEXT_ADAPTER.fetch_add(1, Ordering::Relaxed);
+    let mut adapter = Adapter {
+        name: format!("hci{}", index),
+        description,
+        transport,
+        controller: Controller::new(),
+        state: AdapterState::Starting,
+        address: BluetoothAddress::new([0; 6]),
+        hci_version: 0,
+        inquiring: false,
+        unnamed: VecDeque::new(),
+        naming: None,
+        devices: BTreeMap::new(),
+        links: BTreeMap::new(),
+        pins: BTreeMap::new(),
+        pairing: BTreeMap::new(),
+        receive_failed: false,
+    };
+    adapter.start();
+    ADAPTERS.lock().insert(index, adapter);
+    index
+}
 
-    pub fn unregister_adapter(&self, id: u32) {
-        self.adapters.write().remove(&id);
+/// A controller is gone, and its links with it
+pub fn remove_adapter(index: usize) {
+    let Some(mut adapter) = ADAPTERS.lock().remove(&index) else {
+        return;
+    };
+    let mut notices = Vec::new();
+    for (handle, link) in adapter.links.iter_mut() {
+        link.channels.close_all(LinkId { adapter: index, handle: *handle }, &mut notices);
     }
+    serial_println!("bluetooth: {} removed", adapter.name);
+    profiles::dispatch(notices);
+}
 
-    pub fn get_adapter(&self, id: u32) -> Option<BluetoothAdapter> {
-        self.adapters.read().get(&id).cloned()
-    }
+pub fn adapters() -> Vec<AdapterInfo> {
+    ADAPTERS.lock().iter().map(|(&index, adapter)| adapter.info(index)).collect()
+}
 
-    pub fn get_default_adapter(&self) -> Option<BluetoothAdapter> {
-        self.adapters.read().values().next().cloned()
-    }
+/// The devices an adapter knows of, by address
+pub fn devices(index: Option<usize>) -> Result<Vec<BluetoothDevice>, BluetoothError> {
+    with_adapter(index, |_, adapter, _| Ok(adapter.devices.values().cloned().collect()))
+}
 
-    pub fn list_adapters(&self) -> Vec<u32> {
-        self.adapters.read().keys().cloned().collect()
-    }
+pub fn device(index: Option<usize>, address: BluetoothAddress) -> Option<BluetoothDevice> {
+    with_adapter(index, |_, adapter, _| adapter.devices.get(&address).cloned().ok_or(BluetoothError::InvalidAddress))
+        .ok()
+}
+
+/// Look for devices for about `seconds`, then ask the ones that did not
+/// give their name for it
+pub fn start_inquiry(index: Option<usize>, seconds: u32) -> Result<(), BluetoothError> {
+    with_adapter(index, |_, adapter, _| {
+        if adapter.inquiring {
+            return Err(BluetoothError::ResourceBusy);
+        }
+        // The length is in units of 1.28 seconds
+        let length = (seconds * 100 / 128).clamp(1, 0x30) as u8;
+        let mut parameters = GIAC.to_vec();
+        parameters.extend_from_slice(&[length, 0]);
+        adapter.command(hci::INQUIRY, &parameters);
+        adapter.inquiring = true;
+        Ok(())
+    })
 }
 
-pub static BLUETOOTH_MANAGER: BluetoothManager = BluetoothManager::new();
+/// Whether an inquiry, or the name requests after it, are still going
+pub fn scanning(index: Option<usize>) -> bool {
+    with_adapter(index, |index, adapter, _| Ok(adapter.info(index).scanning)).unwrap_or(false)
+}
 
-pub fn init() {
-    log::info!("Initializing Bluetooth subsystem");
-    
-    // Initialize HCI layer
-    core::hci::init();
-    
-    // Initialize L2CAP layer
-    core::l2cap::init();
-    
-    // Initialize security manager
-    security::init();
-    
-    // Initialize BLE subsystem
-    ble::init();
-    
-    // Scan for Bluetooth adapters
-    scan_for_adapters();
-    
-    log::info!("Bluetooth subsystem initialized");
+/// Connect to a device, pairing with it first if it is not paired, and let
+/// the profiles open their channels; `pin` is for devices from before
+/// Secure Simple Pairing. Returns the adapter used.
+pub fn connect(index: Option<usize>, address: BluetoothAddress, pin: Option<&str>) -> Result<usize, BluetoothError> {
+    with_adapter(index, |index, adapter, notices| {
+        if let Some(pin) = pin {
+            adapter.pins.insert(address, String::from(pin));
+        }
+        if let Some(handle) = adapter.link_of(address) {
+            // Up already: the profiles open what they have not
+            let device = adapter.device(address);
+            device.initiated = true;
+            if device.status == LinkStatus::Connected {
+                let link = LinkId { adapter: index, handle };
+                notices.push(Notice::LinkReady { link, address, class: device.class, initiated: true });
+            }
+            return Ok(index);
+        }
+        let device = adapter.device(address);
+        device.status = LinkStatus::Connecting;
+        device.initiated = true;
+        let mut parameters = address.to_wire().to_vec();
+        parameters.extend_from_slice(&ACL_PACKET_TYPES.to_le_bytes());
+        parameters.extend_from_slice(&[device.page_scan_mode, 0]);
+        let clock_offset = device.clock_offset.map_or(0, |offset| offset | 0x8000);
+        parameters.extend_from_slice(&clock_offset.to_le_bytes());
+        // Allow a role switch
+        parameters.push(1);
+        adapter.command(hci::CREATE_CONNECTION, &parameters);
+        Ok(index)
+    })
 }
 
-fn scan_for_adapters() {
-    // Scan for USB Bluetooth adapters
-    if let Some(addr) = crate::drivers::bluetooth::usb::scan() {
-        BLUETOOTH_MANAGER.register_adapter(addr);
-    }
-    
-    // Scan for UART Bluetooth modules
-    if let Some(addr) = crate::drivers::bluetooth::uart::scan() {
-        BLUETOOTH_MANAGER.register_adapter(addr);
+pub fn disconnect(index: Option<usize>, address: BluetoothAddress) -> Result<(), BluetoothError> {
+    with_adapter(index, |_, adapter, _| {
+        let handle = adapter.link_of(address).ok_or(BluetoothError::NotReady)?;
+        let mut parameters = handle.to_le_bytes().to_vec();
+        parameters.push(REASON_USER_ENDED);
+        adapter.command(hci::DISCONNECT, &parameters);
+        Ok(())
+    })
+}
+
+/// Forget a device's link key, and drop its link
+pub fn unpair(index: Option<usize>, address: BluetoothAddress) -> Result<(), BluetoothError> {
+    with_adapter(index, |_, adapter, _| {
+        let known = security::forget(adapter.address, address);
+        adapter.pins.remove(&address);
+        if let Some(device) = adapter.devices.get_mut(&address) {
+            device.paired = false;
+        }
+        if let Some(handle) = adapter.link_of(address) {
+            let mut parameters = handle.to_le_bytes().to_vec();
+            parameters.push(REASON_USER_ENDED);
+            adapter.command(hci::DISCONNECT, &parameters);
+        } else if !known {
+            return Err(BluetoothError::InvalidAddress);
+        }
+        Ok(())
+    })
+}
+
+/// Handle what the transports have received, and send what waits; called
+/// from the main loop
+pub fn poll() {
+    let mut notices = Vec::new();
+    for (&index, adapter) in ADAPTERS.lock().iter_mut() {
+        adapter.poll(index, &mut notices);
     }
-    
-    // Scan for SDIO Bluetooth chips
-    if let Some(addr) = crate::drivers::bluetooth::sdio::scan() {
-        BLUETOOTH_MANAGER.register_adapter(addr);
+    profiles::dispatch(notices);
+}
+
+/// Poll USB, the serial ports and the stack until `done` says so, for up
+/// to `timeout_ms` milliseconds; for the shell, which waits on the stack
+pub fn wait(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = crate::time::clocksource::now_ns() + timeout_ms * 1_000_000;
+    loop {
+        if done() {
+            return true;
+        }
+        if crate::time::clocksource::now_ns() >= deadline {
+            return false;
+        }
+        crate::serial::poll();
+        crate::usb::urb::poll();
+        poll();
+        core::hint::spin_loop();
     }
-}
\ No newline at end of file
+}
+
+pub fn init() {
+    let profiles: Vec<&str> = profiles::names().collect();
+    serial_println!("bluetooth: profiles: {}", profiles.join(", "));
+}
@@ -0,0 +1,208 @@
+//! The HID profile
+//!
+//! Wireless keyboards and mice, read as a HID host reads them: over a
+//! control channel on PSM 0x11 and an interrupt channel on 0x13. This side
+//! opens both when a peripheral is connected from here, and a paired
+//! device that comes back on its own opens them itself. Once both are open
+//! the device is put in boot protocol, so its input reports are USB boot
+//! reports behind a report ID. They go through `usb::hid`'s keyboard and
+//! mouse drivers to input devices, as a wired one's do.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+
+use spin::Mutex;
+
+use super::Profile;
+use crate::bluetooth::l2cap::{self, ChannelId};
+use crate::bluetooth::{BluetoothAddress, LinkId};
+use crate::input::{self, DeviceKind};
+use crate::serial_println;
+use crate::usb::hid::{KeyboardDriver, MouseDriver};
+
+pub const PSM_CONTROL: u16 = 0x0011;
+pub const PSM_INTERRUPT: u16 = 0x0013;
+
+// Transaction headers, as type << 4 | parameter
+const HANDSHAKE: u8 = 0x00;
+const HID_CONTROL_VIRTUAL_CABLE_UNPLUG: u8 = 0x15;
+const SET_PROTOCOL_BOOT: u8 = 0x70;
+const DATA_INPUT: u8 = 0xA1;
+
+// Boot protocol report IDs
+const REPORT_KEYBOARD: u8 = 0x01;
+const REPORT_MOUSE: u8 = 0x02;
+
+// The major device class peripherals have, in bits 8 to 12
+const MAJOR_PERIPHERAL: u32 = 0x05;
+
+// The screen the mouse driver keeps a pointer within, as USB mice have
+const POINTER_AREA: (u32, u32) = (1024, 768);
+
+struct Device {
+    address: BluetoothAddress,
+    name: String,
+    control: Option<ChannelId>,
+    interrupt: Option<ChannelId>,
+    /// Whether this side opened the control channel, and so opens the
+    /// interrupt one too
+    initiated: bool,
+    /// Input devices, registered at the first report of each kind
+    keyboard: Option<(usize, KeyboardDriver)>,
+    mouse: Option<(usize, MouseDriver)>,
+}
+
+static DEVICES: Mutex<BTreeMap<LinkId, Device>> = Mutex::new(BTreeMap::new());
+
+pub struct HidProfile;
+
+pub static PROFILE: HidProfile = HidProfile;
+
+fn device_name(link: LinkId, address: BluetoothAddress) -> String {
+    crate::bluetooth::device(Some(link.adapter), address)
+        .and_then(|device| device.name)
+        .unwrap_or_else(|| format!("Bluetooth HID {}", address))
+}
+
+fn new_device(link: LinkId, address: BluetoothAddress, initiated: bool) -> Device {
+    Device {
+        address,
+        name: device_name(link, address),
+        control: None,
+        interrupt: None,
+        initiated,
+        keyboard: None,
+        mouse: None,
+    }
+}
+
+impl Device {
+    fn report(&mut self, report: &[u8]) {
+        let [id, data @ ..] = report else {
+            return;
+        };
+        match *id {
+            REPORT_KEYBOARD => {
+                let name = &self.name;
+                let (input, driver) = self
+                    .keyboard
+                    .get_or_insert_with(|| (input::register(name, DeviceKind::Keyboard), KeyboardDriver::new()));
+                driver.process_report(Some(*input), data);
+            }
+            REPORT_MOUSE => {
+                let name = &self.name;
+                let (input, driver) = self.mouse.get_or_insert_with(|| {
+                    (input::register(name, DeviceKind::Mouse), MouseDriver::new(POINTER_AREA.0, POINTER_AREA.1))
+                });
+                driver.process_report(Some(*input), data);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Profile for HidProfile {
+    fn name(&self) -> &'static str {
+        "hid"
+    }
+
+    fn psms(&self) -> &'static [u16] {
+        &[PSM_CONTROL, PSM_INTERRUPT]
+    }
+
+    fn link_ready(&self, link: LinkId, address: BluetoothAddress, class: u32, initiated: bool) {
+        // A device whose class is not known yet is tried anyway
+        let major = (class >> 8) & 0x1F;
+        if !initiated || (class != 0 && major != MAJOR_PERIPHERAL) {
+            return;
+        }
+        {
+            let mut devices = DEVICES.lock();
+            let device = devices.entry(link).or_insert_with(|| new_device(link, address, true));
+            if device.control.is_some() {
+                return;
+            }
+            device.initiated = true;
+        }
+        if let Err(e) = l2cap::connect(link, PSM_CONTROL) {
+            serial_println!("bluetooth: cannot open HID control channel to {}: {:?}", address, e);
+        }
+    }
+
+    fn opened(&self, channel: ChannelId, psm: u16, address: BluetoothAddress) {
+        let (open_interrupt, ready) = {
+            let mut devices = DEVICES.lock();
+            let device = devices.entry(channel.link).or_insert_with(|| new_device(channel.link, address, false));
+            match psm {
+                PSM_CONTROL => device.control = Some(channel),
+                _ => device.interrupt = Some(channel),
+            }
+            let open_interrupt = psm == PSM_CONTROL && device.initiated && device.interrupt.is_none();
+            let ready = device.control.zip(device.interrupt).map(|(control, _)| (control, device.name.clone()));
+            (open_interrupt, ready)
+        };
+        if open_interrupt {
+            if let Err(e) = l2cap::connect(channel.link, PSM_INTERRUPT) {
+                serial_println!("bluetooth: cannot open HID interrupt channel to {}: {:?}", address, e);
+            }
+        }
+        if let Some((control, name)) = ready {
+            serial_println!("bluetooth: {} connected as a HID device", name);
+            if let Err(e) = l2cap::send(control, &[SET_PROTOCOL_BOOT]) {
+                serial_println!("bluetooth: cannot put {} in boot protocol: {:?}", name, e);
+            }
+        }
+    }
+
+    fn data(&self, channel: ChannelId, data: &[u8]) {
+        let unplugged = {
+            let mut devices = DEVICES.lock();
+            let Some(device) = devices.get_mut(&channel.link) else {
+                return;
+            };
+            match data {
+                [DATA_INPUT, report @ ..] if device.interrupt == Some(channel) => {
+                    device.report(report);
+                    None
+                }
+                [header, ..] if *header & 0xF0 == HANDSHAKE && *header != HANDSHAKE => {
+                    serial_println!("bluetooth: {} refused a request: {:#04x}", device.name, header);
+                    None
+                }
+                // The device forgets the pairing, and so should this side
+                [HID_CONTROL_VIRTUAL_CABLE_UNPLUG, ..] => Some(device.address),
+                _ => None,
+            }
+        };
+        if let Some(address) = unplugged {
+            if let Err(e) = crate::bluetooth::unpair(Some(channel.link.adapter), address) {
+                serial_println!("bluetooth: cannot unpair {}: {:?}", address, e);
+            }
+        }
+    }
+
+    fn closed(&self, channel: ChannelId) {
+        let mut devices = DEVICES.lock();
+        let Some(device) = devices.get_mut(&channel.link) else {
+            return;
+        };
+        if device.control == Some(channel) {
+            device.control = None;
+        }
+        if device.interrupt == Some(channel) {
+            device.interrupt = None;
+        }
+        if device.control.is_some() || device.interrupt.is_some() {
+            return;
+        }
+        if let Some(device) = devices.remove(&channel.link) {
+            for input in
+                [device.keyboard.map(|(input, _)| input), device.mouse.map(|(input, _)| input)].into_iter().flatten()
+            {
+                input::unregister(input);
+            }
+            serial_println!("bluetooth: {} disconnected", device.name);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Profiles
+//!
+//! A profile serves a set of PSMs: it takes the channels devices open to
+//! them and opens its own, and is told when a link is ready to carry them.
+//! Profiles are told from `bluetooth::poll` and the calls that make the
+//! stack act, once its lock is let go, so they may call back into
+//! `l2cap`. Another profile, such as A2DP on AVDTP's PSM, is a `Profile`
+//! added to `PROFILES`.
+
+pub mod hid;
+
+use alloc::vec::Vec;
+
+use super::l2cap::ChannelId;
+use super::{BluetoothAddress, LinkId};
+
+pub trait Profile: Sync {
+    fn name(&self) -> &'static str;
+    /// The PSMs whose channels it takes
+    fn psms(&self) -> &'static [u16];
+    /// A link is authenticated and encrypted. `initiated` is set when it
+    /// was asked for here, and a profile then opens the channels it needs
+    /// if the device, by its class of device, is one it serves.
+    fn link_ready(&self, link: LinkId, address: BluetoothAddress, class: u32, initiated: bool);
+    fn opened(&self, channel: ChannelId, psm: u16, address: BluetoothAddress);
+    fn data(&self, channel: ChannelId, data: &[u8]);
+    /// The channel is closed, or was refused before it opened
+    fn closed(&self, channel: ChannelId);
+}
+
+static PROFILES: &[&dyn Profile] = &[&hid::PROFILE];
+
+/// What profiles are told, gathered under the stack's lock
+#[derive(Debug)]
+pub(crate) enum Notice {
+    LinkReady { link: LinkId, address: BluetoothAddress, class: u32, initiated: bool },
+    Opened { channel: ChannelId, psm: u16, address: BluetoothAddress },
+    Data { channel: ChannelId, psm: u16, data: Vec<u8> },
+    Closed { channel: ChannelId, psm: u16 },
+}
+
+fn serving(psm: u16) -> Option<&'static dyn Profile> {
+    PROFILES.iter().copied().find(|profile| profile.psms().contains(&psm))
+}
+
+/// Whether a profile takes channels to a PSM
+pub(crate) fn serves(psm: u16) -> bool {
+    serving(psm).is_some()
+}
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    PROFILES.iter().map(|profile| profile.name())
+}
+
+/// Tell the profiles; called with no stack lock held
+pub(crate) fn dispatch(notices: Vec<Notice>) {
+    for notice in notices {
+        match notice {
+            Notice::LinkReady { link, address, class, initiated } => {
+                for profile in PROFILES {
+                    profile.link_ready(link, address, class, initiated);
+                }
+            }
+            Notice::Opened { channel, psm, address } => {
+                if let Some(profile) = serving(psm) {
+                    profile.opened(channel, psm, address);
+                }
+            }
+            Notice::Data { channel, psm, data } => {
+                if let Some(profile) = serving(psm) {
+                    profile.data(channel, &data);
+                }
+            }
+            Notice::Closed { channel, psm } => {
+                if let Some(profile) = serving(psm) {
+                    profile.closed(channel);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Pairing and link keys
+//!
+//! Secure Simple Pairing, with this side as DisplayYesNo. A keyboard gets
+//! a passkey to type, which is printed on the console. A device with a
+//! display is sent a number to compare; it is printed too and accepted
+//! here, so the comparison is made on the device. A device with neither
+//! pairs with Just Works. Devices from before 2.1 pair with the PIN given
+//! to `bluetooth connect`, and are refused without one.
+//!
+//! Bonds are kept in the registry where Windows keeps them, under
+//! BTHPORT's Keys key: a subkey for each adapter, named by its address in
+//! hex, and in it one for each device with its link key, the key's type,
+//! and the device's name and class of device.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::BluetoothAddress;
+use crate::nt::registry::{RegistryValue, REGISTRY_MANAGER};
+
+const KEYS: &str = "HKLM\\SYSTEM\\CurrentControlSet\\Services\\BTHPORT\\Parameters\\Keys";
+
+// IO capabilities, as IO Capability Request Reply gives them
+pub const DISPLAY_ONLY: u8 = 0x00;
+pub const DISPLAY_YES_NO: u8 = 0x01;
+pub const KEYBOARD_ONLY: u8 = 0x02;
+pub const NO_INPUT_NO_OUTPUT: u8 = 0x03;
+
+/// What this side can do
+pub const IO_CAPABILITY: u8 = DISPLAY_YES_NO;
+/// MITM protection, and general bonding: the key is kept
+pub const AUTHENTICATION: u8 = 0x05;
+
+/// A device this adapter has a link key for
+#[derive(Debug, Clone)]
+pub struct Bond {
+    pub address: BluetoothAddress,
+    pub key: [u8; 16],
+    pub key_type: u8,
+    pub name: Option<String>,
+    pub class: u32,
+}
+
+/// Whether a device with this IO capability shows the number it is asked
+/// to compare, so it is worth printing
+pub fn shows_number(capability: u8) -> bool {
+    matches!(capability, DISPLAY_ONLY | DISPLAY_YES_NO)
+}
+
+fn adapter_path(adapter: BluetoothAddress) -> String {
+    format!("{}\\{}", KEYS, adapter.hex())
+}
+
+pub fn link_key(adapter: BluetoothAddress, device: BluetoothAddress) -> Option<[u8; 16]> {
+    let path = format!("{}\\{}", adapter_path(adapter), device.hex());
+    let registry = REGISTRY_MANAGER.lock();
+    let key = registry.get_key_by_path(&path)?.get_value("LinkKey")?.as_binary()?;
+    key.try_into().ok()
+}
+
+pub fn store(adapter: BluetoothAddress, bond: &Bond) {
+    let path = format!("{}\\{}", adapter_path(adapter), bond.address.hex());
+    let mut registry = REGISTRY_MANAGER.lock();
+    let Some(key) = registry.create_key_by_path(&path) else {
+        return;
+    };
+    key.set_value("LinkKey".to_string(), RegistryValue::new_binary("LinkKey".to_string(), bond.key.to_vec()));
+    key.set_value("KeyType".to_string(), RegistryValue::new_dword("KeyType".to_string(), bond.key_type as u32));
+    key.set_value("COD".to_string(), RegistryValue::new_dword("COD".to_string(), bond.class));
+    if let Some(name) = &bond.name {
+        key.set_value("Name".to_string(), RegistryValue::new_string("Name".to_string(), name.clone()));
+    }
+}
+
+/// Drop a device's link key; false if there was none
+pub fn forget(adapter: BluetoothAddress, device: BluetoothAddress) -> bool {
+    let path = format!("{}\\{}", adapter_path(adapter), device.hex());
+    REGISTRY_MANAGER.lock().delete_key_by_path(&path)
+}
+
+pub fn bonds(adapter: BluetoothAddress) -> Vec<Bond> {
+    let registry = REGISTRY_MANAGER.lock();
+    let path = adapter_path(adapter);
+    let Some(keys) = registry.get_key_by_path(&path) else {
+        return Vec::new();
+    };
+    keys.enumerate_subkeys()
+        .into_iter()
+        .filter_map(|subkey| {
+            let device = registry.get_key_by_path(&format!("{}\\{}", path, subkey))?;
+            let key = device.get_value("LinkKey")?.as_binary()?.try_into().ok()?;
+            let dword = |value: &str| device.get_value(value).and_then(RegistryValue::as_dword).unwrap_or(0);
+            let name = device.get_value("Name").and_then(RegistryValue::as_string);
+            Some(Bond {
+                address: BluetoothAddress::from_hex(&subkey)?,
+                key,
+                key_type: dword("KeyType") as u8,
+                name,
+                class: dword("COD"),
+            })
+        })
+        .collect()
+}
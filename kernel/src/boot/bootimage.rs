@@ -0,0 +1,29 @@
+//! The `bootloader` crate's loader, used by `cargo bootimage`
+//!
+//! It hands `_start` a pointer to its own boot information: a memory map
+//! that already marks the kernel, its stack and page tables as in use, and
+//! the offset it mapped physical memory at. It has no command line,
+//! modules or framebuffer to pass, so the built-in command line applies,
+//! and ACPI tables are found by scanning the BIOS areas.
+
+use bootloader::bootinfo::{BootInfo as LoaderInfo, MemoryRegionType as LoaderRegion};
+
+use super::{BootInfo, MemoryRegionType, Protocol};
+
+pub fn parse(loader: &'static LoaderInfo) -> BootInfo {
+    let mut info = BootInfo::new(Protocol::Bootimage, loader.physical_memory_offset);
+    info.set_loader(b"bootloader 0.9");
+    for region in loader.memory_map.iter() {
+        let region_type = match region.region_type {
+            LoaderRegion::Usable => MemoryRegionType::Usable,
+            LoaderRegion::AcpiReclaimable => MemoryRegionType::AcpiReclaimable,
+            LoaderRegion::AcpiNvs => MemoryRegionType::AcpiNvs,
+            LoaderRegion::BadMemory => MemoryRegionType::BadMemory,
+            LoaderRegion::Kernel | LoaderRegion::KernelStack | LoaderRegion::PageTable => MemoryRegionType::Kernel,
+            LoaderRegion::Bootloader | LoaderRegion::BootInfo | LoaderRegion::Package => MemoryRegionType::Bootloader,
+            _ => MemoryRegionType::Reserved,
+        };
+        info.add_region(region.range.start_addr(), region.range.end_addr(), region_type);
+    }
+    info
+}
@@ -0,0 +1,202 @@
+//! Kernel command line options
+//!
+//! A subsystem declares each option it reads as a `Param` static next to
+//! the code that uses it, and reads it with `get`. The first read registers
+//! the option, so `effective()` lists every option the kernel has looked
+//! at, with the value in force and whether it came from the command line.
+//!
+//! Options are `name=value` words, or a bare `name` for a flag; when one is
+//! given twice the last wins. Names are dotted by subsystem where one has
+//! several, as in `security.kaslr=off`. A value that does not parse is
+//! reported once and the default used instead.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+/// A type an option's value parses into
+pub trait Value: Copy + Send + Sync + 'static {
+    /// What the value looks like, for listings
+    const KIND: &'static str;
+
+    /// `text` is "" for a bare word
+    fn parse(text: &'static str) -> Option<Self>;
+
+    fn show(&self) -> String;
+}
+
+impl Value for bool {
+    const KIND: &'static str = "bool";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "" | "1" | "y" | "yes" | "on" | "true" => Some(true),
+            "0" | "n" | "no" | "off" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    fn show(&self) -> String {
+        String::from(if *self { "on" } else { "off" })
+    }
+}
+
+impl Value for i64 {
+    const KIND: &'static str = "int";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let value = match digits.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+            None => digits.parse().ok()?,
+        };
+        Some(if negative { -value } else { value })
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Value for &'static str {
+    const KIND: &'static str = "string";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        Some(text)
+    }
+
+    fn show(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Where an option's effective value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    CommandLine,
+    /// The command line gave a value that did not parse
+    Invalid,
+}
+
+/// One registered option, as `effective()` reports it
+#[derive(Debug, Clone)]
+pub struct Effective {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub value: String,
+    pub default: String,
+    pub source: Source,
+    pub help: &'static str,
+}
+
+// What the registry keeps of a Param, whatever its type
+trait Registered: Sync {
+    fn param_name(&self) -> &'static str;
+    fn effective(&self) -> Effective;
+}
+
+static REGISTRY: Mutex<Vec<&'static dyn Registered>> = Mutex::new(Vec::new());
+
+/// A command line option
+pub struct Param<T: Value> {
+    name: &'static str,
+    default: T,
+    help: &'static str,
+    registered: AtomicBool,
+}
+
+impl<T: Value> Param<T> {
+    pub const fn new(name: &'static str, default: T, help: &'static str) -> Self {
+        Param { name, default, help, registered: AtomicBool::new(false) }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The option's text on the command line, if it is there
+    pub fn raw(&self) -> Option<&'static str> {
+        super::info()?.param(self.name)
+    }
+
+    fn lookup(&self) -> (T, Source) {
+        match self.raw() {
+            None => (self.default, Source::Default),
+            Some(text) => match T::parse(text) {
+                Some(value) => (value, Source::CommandLine),
+                None => (self.default, Source::Invalid),
+            },
+        }
+    }
+
+    /// The effective value
+    pub fn get(&'static self) -> T {
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            REGISTRY.lock().push(self);
+            if let (Some(text), (_, Source::Invalid)) = (self.raw(), self.lookup()) {
+                crate::serial_println!(
+                    "[CMDLINE] {}={}: not a valid {}, using {}",
+                    self.name,
+                    text,
+                    T::KIND,
+                    self.default.show()
+                );
+            }
+        }
+        self.lookup().0
+    }
+
+    /// Whether the command line gave the option a value that parsed
+    pub fn is_set(&'static self) -> bool {
+        self.get();
+        self.lookup().1 == Source::CommandLine
+    }
+}
+
+impl<T: Value> Registered for Param<T> {
+    fn param_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn effective(&self) -> Effective {
+        let (value, source) = self.lookup();
+        Effective {
+            name: self.name,
+            kind: T::KIND,
+            value: value.show(),
+            default: self.default.show(),
+            source,
+            help: self.help,
+        }
+    }
+}
+
+/// The command line in use: the loader's, or the built-in one
+pub fn raw() -> &'static str {
+    super::info().map_or("", |info| info.cmdline())
+}
+
+/// Every option read so far, by name
+pub fn effective() -> Vec<Effective> {
+    let mut options: Vec<Effective> = REGISTRY.lock().iter().map(|param| param.effective()).collect();
+    options.sort_by_key(|option| option.name);
+    options
+}
+
+/// Words on the command line that no option read so far is named by
+pub fn unrecognized() -> Vec<&'static str> {
+    let registry = REGISTRY.lock();
+    raw()
+        .split_whitespace()
+        .filter(|word| {
+            let name = word.split_once('=').map_or(*word, |(name, _)| name);
+            !registry.iter().any(|param| param.param_name() == name)
+        })
+        .collect()
+}
@@ -0,0 +1,260 @@
+//! The Limine boot protocol
+//!
+//! Limine loads the kernel in long mode at its higher-half link address
+//! (linker/limine.ld) and jumps to `limine_start`. The kernel asks for what
+//! it wants with request structures in the `.requests` section; the loader
+//! finds them by their IDs and fills in pointers to its responses, which
+//! stay valid until bootloader-reclaimable memory is reused, which the
+//! kernel never does.
+//!
+//! No base revision tag is given, so base revision 0 applies: the first
+//! 4 GiB stay identity-mapped beside the higher-half direct map, which the
+//! VGA text buffer and the BIOS areas are still reached through, and
+//! response addresses are virtual.
+
+use core::cell::UnsafeCell;
+use core::ffi::CStr;
+
+use super::{BootInfo, ColorField, Framebuffer, FramebufferFormat, MemoryRegionType, Protocol};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+
+#[repr(C)]
+struct Request<R> {
+    id: [u64; 4],
+    revision: u64,
+    // Written by the loader
+    response: UnsafeCell<*const R>,
+}
+
+// Only the loader writes them, before the kernel runs
+unsafe impl<R> Sync for Request<R> {}
+
+impl<R> Request<R> {
+    const fn new(id: [u64; 2]) -> Self {
+        Request {
+            id: [COMMON_MAGIC[0], COMMON_MAGIC[1], id[0], id[1]],
+            revision: 0,
+            response: UnsafeCell::new(core::ptr::null()),
+        }
+    }
+
+    fn response(&self) -> Option<&'static R> {
+        unsafe { self.response.get().read_volatile().as_ref() }
+    }
+}
+
+#[repr(C)]
+struct BootloaderInfoResponse {
+    revision: u64,
+    name: *const u8,
+    version: *const u8,
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: *const *const MemmapEntry,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    kind: u64,
+}
+
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_RESERVED: u64 = 1;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BAD_MEMORY: u64 = 4;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+const MEMMAP_FRAMEBUFFER: u64 = 7;
+
+#[repr(C)]
+struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: *const *const LimineFramebuffer,
+}
+
+#[repr(C)]
+struct LimineFramebuffer {
+    address: u64,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+}
+
+const MEMORY_MODEL_RGB: u8 = 1;
+
+#[repr(C)]
+struct RsdpResponse {
+    revision: u64,
+    address: u64,
+}
+
+#[repr(C)]
+struct File {
+    revision: u64,
+    address: u64,
+    size: u64,
+    path: *const u8,
+    cmdline: *const u8,
+}
+
+#[repr(C)]
+struct KernelFileResponse {
+    revision: u64,
+    kernel_file: *const File,
+}
+
+#[repr(C)]
+struct ModuleResponse {
+    revision: u64,
+    module_count: u64,
+    modules: *const *const File,
+}
+
+#[used]
+#[link_section = ".requests"]
+static BOOTLOADER_INFO: Request<BootloaderInfoResponse> = Request::new([0xf55038d8e2a1202f, 0x279426fcf5f59740]);
+
+#[used]
+#[link_section = ".requests"]
+static HHDM: Request<HhdmResponse> = Request::new([0x48dcf1cb8ad2b852, 0x63984e959a98244b]);
+
+#[used]
+#[link_section = ".requests"]
+static MEMMAP: Request<MemmapResponse> = Request::new([0x67cf3d9d378a806f, 0xe304acdfc50c3c62]);
+
+#[used]
+#[link_section = ".requests"]
+static FRAMEBUFFER: Request<FramebufferResponse> = Request::new([0x9d5827dcd881dd75, 0xa3148604f6fab11b]);
+
+#[used]
+#[link_section = ".requests"]
+static RSDP: Request<RsdpResponse> = Request::new([0xc5e77b6b397e7b43, 0x27637845accdcf3c]);
+
+#[used]
+#[link_section = ".requests"]
+static KERNEL_FILE: Request<KernelFileResponse> = Request::new([0xad97e90e83f1ed67, 0x31eb5d1c5ff23b69]);
+
+#[used]
+#[link_section = ".requests"]
+static MODULES: Request<ModuleResponse> = Request::new([0x3e7e279702be32af, 0xca1c4f3bd1280cee]);
+
+// A loader-owned array of `count` pointers
+unsafe fn entries<T: 'static>(list: *const *const T, count: u64) -> impl Iterator<Item = &'static T> {
+    (0..count as usize).filter_map(move |i| unsafe { (*list.add(i)).as_ref() })
+}
+
+unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    if ptr.is_null() {
+        return &[];
+    }
+    unsafe { CStr::from_ptr(ptr.cast()).to_bytes() }
+}
+
+fn file_name(path: &[u8]) -> &[u8] {
+    path.rsplit(|&byte| byte == b'/').next().unwrap_or(path)
+}
+
+fn parse() -> BootInfo {
+    let offset = HHDM.response().map_or(0, |hhdm| hhdm.offset);
+    // Responses give higher-half addresses
+    let physical = |address: u64| address.checked_sub(offset).unwrap_or(address);
+    let mut info = BootInfo::new(Protocol::Limine, offset);
+
+    if let Some(loader) = BOOTLOADER_INFO.response() {
+        let (loader_name, version) = unsafe { (c_str(loader.name), c_str(loader.version)) };
+        let mut name = [0; 64];
+        let mut len = 0;
+        for &byte in loader_name.iter().chain(b" ").chain(version).take(name.len()) {
+            name[len] = byte;
+            len += 1;
+        }
+        info.set_loader(&name[..len]);
+    }
+
+    if let Some(memmap) = MEMMAP.response() {
+        for entry in unsafe { entries(memmap.entries, memmap.entry_count) } {
+            let region_type = match entry.kind {
+                MEMMAP_USABLE => MemoryRegionType::Usable,
+                MEMMAP_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+                MEMMAP_ACPI_NVS => MemoryRegionType::AcpiNvs,
+                MEMMAP_BAD_MEMORY => MemoryRegionType::BadMemory,
+                // Page tables, the stack and these responses live there
+                MEMMAP_BOOTLOADER_RECLAIMABLE => MemoryRegionType::Bootloader,
+                MEMMAP_KERNEL_AND_MODULES => MemoryRegionType::Kernel,
+                MEMMAP_FRAMEBUFFER => MemoryRegionType::FrameBuffer,
+                MEMMAP_RESERVED => MemoryRegionType::Reserved,
+                // Types added to the protocol since
+                _ => MemoryRegionType::Reserved,
+            };
+            info.add_region(entry.base, entry.base + entry.length, region_type);
+        }
+    }
+
+    let framebuffer = FRAMEBUFFER
+        .response()
+        .and_then(|response| unsafe { entries(response.framebuffers, response.framebuffer_count) }.next());
+    if let Some(framebuffer) = framebuffer {
+        let format = if framebuffer.memory_model == MEMORY_MODEL_RGB {
+            FramebufferFormat::Rgb {
+                red: ColorField { shift: framebuffer.red_mask_shift, size: framebuffer.red_mask_size },
+                green: ColorField { shift: framebuffer.green_mask_shift, size: framebuffer.green_mask_size },
+                blue: ColorField { shift: framebuffer.blue_mask_shift, size: framebuffer.blue_mask_size },
+            }
+        } else {
+            FramebufferFormat::Indexed
+        };
+        info.framebuffer = Some(Framebuffer {
+            address: physical(framebuffer.address),
+            width: framebuffer.width as u32,
+            height: framebuffer.height as u32,
+            pitch: framebuffer.pitch as u32,
+            bpp: framebuffer.bpp as u8,
+            format,
+        });
+    }
+
+    info.rsdp = RSDP.response().map(|rsdp| physical(rsdp.address)).filter(|&address| address != 0);
+
+    if let Some(kernel) = KERNEL_FILE.response().and_then(|response| unsafe { response.kernel_file.as_ref() }) {
+        info.set_cmdline(unsafe { c_str(kernel.cmdline) });
+    }
+
+    if let Some(modules) = MODULES.response() {
+        for module in unsafe { entries(modules.modules, modules.module_count) } {
+            let (path, cmdline) = unsafe { (c_str(module.path), c_str(module.cmdline)) };
+            let name = if cmdline.is_empty() { file_name(path) } else { cmdline };
+            info.add_module(physical(module.address), module.size, name);
+        }
+    }
+    info
+}
+
+/// Limine's entry point, named by linker/limine.ld
+#[no_mangle]
+extern "C" fn limine_start() -> ! {
+    super::set(parse());
+    crate::kernel_main()
+}
@@ -0,0 +1,379 @@
+//! Boot protocols
+//!
+//! The kernel can be started by the bootimage loader it has always used, by
+//! a Multiboot2 loader such as GRUB (the `multiboot2` feature), by Limine
+//! (the `limine` feature) or by the project's UEFI loader (the `uefi`
+//! feature). Each entry point turns what its loader passed into one
+//! `BootInfo` before anything else runs, so the rest of the kernel never
+//! sees the protocol: the firmware memory map, where physical memory is
+//! mapped, the framebuffer, the ACPI RSDP, the command line and the loaded
+//! modules, such as an initrd.
+//!
+//! It is kept in fixed-size tables, as it is gathered before there is a
+//! heap and before the loader's own memory may be reused.
+//!
+//! A loader that passes no command line gets the one built in from
+//! `KERNEL_CMDLINE`; cmdline.rs reads options from it.
+
+pub mod bootimage;
+pub mod cmdline;
+#[cfg(feature = "limine")]
+pub mod limine;
+#[cfg(feature = "multiboot2")]
+pub mod multiboot2;
+#[cfg(feature = "uefi")]
+pub mod uefi;
+
+#[cfg(any(
+    all(feature = "limine", feature = "multiboot2"),
+    all(feature = "limine", feature = "uefi"),
+    all(feature = "multiboot2", feature = "uefi")
+))]
+compile_error!("the limine, multiboot2 and uefi features each lay out the kernel for their loader; enable one");
+
+use alloc::vec::Vec;
+
+use spin::Once;
+
+use crate::memory::PHYS_MEM_OFFSET;
+pub use crate::memory::physical::{MemoryRegion, MemoryRegionType};
+
+pub const MAX_REGIONS: usize = 256;
+pub const MAX_MODULES: usize = 16;
+pub const MAX_CMDLINE: usize = 512;
+const MAX_NAME: usize = 64;
+const MAX_RESERVED: usize = 8;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Built in with KERNEL_CMDLINE=..., for loaders that pass no command line
+const BUILTIN_CMDLINE: Option<&str> = option_env!("KERNEL_CMDLINE");
+
+// Real-mode memory: the BIOS data areas, and where APs are started
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// The `bootloader` crate's loader, as built by `cargo bootimage`
+    Bootimage,
+    Multiboot2,
+    Limine,
+    /// bootloader/ built as an EFI application
+    Uefi,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Bootimage => "bootimage",
+            Protocol::Multiboot2 => "Multiboot2",
+            Protocol::Limine => "Limine",
+            Protocol::Uefi => "UEFI",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorField {
+    pub shift: u8,
+    pub size: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferFormat {
+    Rgb { red: ColorField, green: ColorField, blue: ColorField },
+    /// Palette indices
+    Indexed,
+    /// The VGA text buffer: character and attribute pairs
+    Text,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    /// Physical address
+    pub address: u64,
+    /// In pixels, or characters for text
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line
+    pub pitch: u32,
+    pub bpp: u8,
+    pub format: FramebufferFormat,
+}
+
+// Text copied out of loader memory
+#[derive(Clone, Copy)]
+struct Name<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Name<N> {
+    const EMPTY: Self = Name { bytes: [0; N], len: 0 };
+
+    // Cut at N bytes, on a character boundary
+    fn new(bytes: &[u8]) -> Self {
+        let text = match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or(""),
+        };
+        let mut len = text.len().min(N);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut name = Self::EMPTY;
+        name.bytes[..len].copy_from_slice(&text.as_bytes()[..len]);
+        name.len = len;
+        name
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+/// A file the loader put in memory for the kernel
+#[derive(Clone, Copy)]
+pub struct Module {
+    /// Physical address
+    pub start: u64,
+    pub size: u64,
+    name: Name<MAX_NAME>,
+}
+
+impl Module {
+    /// The module's command line, or its file name without one
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Contents, through the direct map
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts((PHYS_MEM_OFFSET + self.start) as *const u8, self.size as usize) }
+    }
+}
+
+pub struct BootInfo {
+    pub protocol: Protocol,
+    /// Where the loader mapped physical memory
+    pub phys_offset: u64,
+    /// How much of physical memory is mapped there
+    pub direct_map_end: u64,
+    pub framebuffer: Option<Framebuffer>,
+    /// Physical address of the ACPI RSDP
+    pub rsdp: Option<u64>,
+    /// Firmware Secure Boot was on and the loader verified the kernel's
+    /// signature
+    pub secure_boot: bool,
+    regions: [MemoryRegion; MAX_REGIONS],
+    region_count: usize,
+    dropped_regions: usize,
+    modules: [Module; MAX_MODULES],
+    module_count: usize,
+    cmdline: Name<MAX_CMDLINE>,
+    loader: Name<MAX_NAME>,
+    // Usable memory the loader left something in that the kernel keeps
+    // using, beyond the modules
+    reserved: [(u64, u64); MAX_RESERVED],
+    reserved_count: usize,
+}
+
+impl BootInfo {
+    pub fn new(protocol: Protocol, phys_offset: u64) -> Self {
+        BootInfo {
+            protocol,
+            phys_offset,
+            direct_map_end: u64::MAX,
+            framebuffer: None,
+            rsdp: None,
+            secure_boot: false,
+            regions: [MemoryRegion { start: 0, end: 0, region_type: MemoryRegionType::Reserved }; MAX_REGIONS],
+            region_count: 0,
+            dropped_regions: 0,
+            modules: [Module { start: 0, size: 0, name: Name::EMPTY }; MAX_MODULES],
+            module_count: 0,
+            cmdline: Name::EMPTY,
+            loader: Name::EMPTY,
+            reserved: [(0, 0); MAX_RESERVED],
+            reserved_count: 0,
+        }
+    }
+
+    pub fn add_region(&mut self, start: u64, end: u64, region_type: MemoryRegionType) {
+        if start >= end {
+            return;
+        }
+        match self.regions.get_mut(self.region_count) {
+            Some(region) => {
+                *region = MemoryRegion { start, end, region_type };
+                self.region_count += 1;
+            }
+            None => self.dropped_regions += 1,
+        }
+    }
+
+    pub fn add_module(&mut self, start: u64, size: u64, name: &[u8]) {
+        if let Some(module) = self.modules.get_mut(self.module_count) {
+            *module = Module { start, size, name: Name::new(name) };
+            self.module_count += 1;
+        }
+    }
+
+    pub fn set_cmdline(&mut self, cmdline: &[u8]) {
+        self.cmdline = Name::new(cmdline);
+    }
+
+    pub fn set_loader(&mut self, name: &[u8]) {
+        self.loader = Name::new(name);
+    }
+
+    /// Keep `start..end` out of the frame allocator's hands
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        if let Some(range) = self.reserved.get_mut(self.reserved_count) {
+            *range = (start, end);
+            self.reserved_count += 1;
+        }
+    }
+
+    /// The firmware memory map as the loader gave it
+    pub fn memory_map(&self) -> &[MemoryRegion] {
+        &self.regions[..self.region_count]
+    }
+
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[..self.module_count]
+    }
+
+    pub fn module(&self, name: &str) -> Option<&Module> {
+        self.modules().iter().find(|module| module.name() == name)
+    }
+
+    pub fn cmdline(&self) -> &str {
+        self.cmdline.as_str()
+    }
+
+    /// The value of `name=value` on the command line, or "" for a bare
+    /// `name`; the last one wins if it is given twice
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.cmdline().split_whitespace().rev().find_map(|word| match word.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            None if word == name => Some(""),
+            _ => None,
+        })
+    }
+
+    /// The loader's name and version, if it gave them
+    pub fn loader(&self) -> &str {
+        self.loader.as_str()
+    }
+
+    /// Page-aligned ranges of RAM free for the kernel to allocate: usable
+    /// memory, less real-mode memory, the modules, what the loader left in
+    /// use and anything outside the direct map
+    pub fn usable_memory(&self) -> Vec<(u64, u64)> {
+        let mut holes: Vec<(u64, u64)> = self.reserved[..self.reserved_count]
+            .iter()
+            .copied()
+            .chain(self.modules().iter().map(|module| (module.start, module.start + module.size)))
+            .chain([(0, LOW_MEMORY_END)])
+            .collect();
+        holes.sort_unstable();
+
+        let mut usable = Vec::new();
+        for region in self.memory_map().iter().filter(|region| region.region_type == MemoryRegionType::Usable) {
+            let mut start = align_up(region.start);
+            let end = align_down(region.end.min(self.direct_map_end));
+            for &(hole_start, hole_end) in &holes {
+                if hole_end <= start || hole_start >= end {
+                    continue;
+                }
+                if align_down(hole_start) > start {
+                    usable.push((start, align_down(hole_start)));
+                }
+                start = start.max(align_up(hole_end));
+            }
+            if start < end {
+                usable.push((start, end));
+            }
+        }
+        usable
+    }
+
+    fn print_summary(&self) {
+        crate::serial_println!("[BOOT] Started by {} loader {}", self.protocol.name(), self.loader());
+        for region in self.memory_map() {
+            crate::serial_println!("[BOOT] mem {:#018x}-{:#018x} {:?}", region.start, region.end - 1, region.region_type);
+        }
+        if self.dropped_regions > 0 {
+            crate::serial_println!("[BOOT] {} memory map entries past the first {} ignored", self.dropped_regions, MAX_REGIONS);
+        }
+        let usable: u64 = self.usable_memory().iter().map(|(start, end)| end - start).sum();
+        crate::serial_println!("[BOOT] {} MiB free for the kernel", usable / (1024 * 1024));
+        crate::serial_println!("[BOOT] command line: \"{}\"", self.cmdline());
+        for module in self.modules() {
+            crate::serial_println!(
+                "[BOOT] module \"{}\" at {:#x}, {} bytes",
+                module.name(),
+                module.start,
+                module.size
+            );
+        }
+        if let Some(framebuffer) = self.framebuffer {
+            crate::serial_println!(
+                "[BOOT] framebuffer {}x{}x{} at {:#x}, {:?}",
+                framebuffer.width,
+                framebuffer.height,
+                framebuffer.bpp,
+                framebuffer.address,
+                framebuffer.format
+            );
+        }
+        if let Some(rsdp) = self.rsdp {
+            crate::serial_println!("[BOOT] ACPI RSDP at {:#x}", rsdp);
+        }
+        if self.secure_boot {
+            crate::serial_println!("[BOOT] Kernel signature verified under Secure Boot");
+        }
+    }
+}
+
+fn align_up(address: u64) -> u64 {
+    address.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn align_down(address: u64) -> u64 {
+    address & !(PAGE_SIZE - 1)
+}
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Record what the loader passed. Everything else relies on physical
+/// memory being mapped at `PHYS_MEM_OFFSET`, so a loader that put it
+/// elsewhere is refused here.
+pub fn set(mut info: BootInfo) {
+    if info.phys_offset != PHYS_MEM_OFFSET {
+        panic!(
+            "{} mapped physical memory at {:#x}; the kernel expects it at {:#x}",
+            info.protocol.name(),
+            info.phys_offset,
+            PHYS_MEM_OFFSET
+        );
+    }
+    if info.cmdline().is_empty() {
+        info.set_cmdline(BUILTIN_CMDLINE.unwrap_or("").as_bytes());
+    }
+    BOOT_INFO.call_once(|| info);
+}
+
+/// What the loader passed, once an entry point has recorded it
+pub fn info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
+
+/// Log what the loader passed
+pub fn print_summary() {
+    match info() {
+        Some(info) => info.print_summary(),
+        None => crate::serial_println!("[BOOT] No boot information"),
+    }
+}
@@ -0,0 +1,143 @@
+//! The Multiboot2 boot protocol
+//!
+//! A Multiboot2 loader such as GRUB finds the header in multiboot2_entry.S
+//! within the image's first 32 KiB and enters `multiboot2_entry` in 32-bit
+//! protected mode. That stub maps the first 4 GiB both where the kernel is
+//! linked, low, and at `PHYS_MEM_OFFSET`, switches to long mode and calls
+//! `multiboot2_main` with the boot information's physical address.
+//!
+//! The information is a list of tags; the memory map it gives marks the
+//! kernel, modules and the information itself as available, so they are
+//! reserved here.
+
+use core::arch::global_asm;
+
+use super::{BootInfo, ColorField, Framebuffer, FramebufferFormat, MemoryRegionType, Protocol};
+use crate::memory::PHYS_MEM_OFFSET;
+
+global_asm!(include_str!("multiboot2_entry.S"), options(att_syntax));
+
+/// What the loader leaves in %eax
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// How much physical memory the entry stub maps
+pub const DIRECT_MAP_SIZE: u64 = 4 << 30;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_ACPI_NVS: u32 = 4;
+const MMAP_BAD: u32 = 5;
+
+const FRAMEBUFFER_INDEXED: u8 = 0;
+const FRAMEBUFFER_RGB: u8 = 1;
+
+extern "C" {
+    // Set by linker/multiboot2.ld, where the kernel is linked at its
+    // physical address
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+unsafe fn read<T: Copy>(address: u64) -> T {
+    unsafe { ((PHYS_MEM_OFFSET + address) as *const T).read_unaligned() }
+}
+
+// Up to the terminating NUL, within `len` bytes
+unsafe fn c_str(address: u64, len: u32) -> &'static [u8] {
+    let bytes = unsafe { core::slice::from_raw_parts((PHYS_MEM_OFFSET + address) as *const u8, len as usize) };
+    bytes.split(|&byte| byte == 0).next().unwrap_or(bytes)
+}
+
+unsafe fn parse_mmap(info: &mut BootInfo, tag: u64, size: u32) {
+    let entry_size = unsafe { read::<u32>(tag + 8) } as u64;
+    if entry_size < 20 {
+        return;
+    }
+    let mut entry = tag + 16;
+    while entry + entry_size <= tag + size as u64 {
+        let (base, length, kind) = unsafe { (read::<u64>(entry), read::<u64>(entry + 8), read::<u32>(entry + 16)) };
+        let region_type = match kind {
+            MMAP_AVAILABLE => MemoryRegionType::Usable,
+            MMAP_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+            MMAP_ACPI_NVS => MemoryRegionType::AcpiNvs,
+            MMAP_BAD => MemoryRegionType::BadMemory,
+            _ => MemoryRegionType::Reserved,
+        };
+        info.add_region(base, base.saturating_add(length), region_type);
+        entry += entry_size;
+    }
+}
+
+unsafe fn parse_framebuffer(tag: u64) -> Framebuffer {
+    let format = match unsafe { read::<u8>(tag + 29) } {
+        FRAMEBUFFER_RGB => {
+            let field = |offset| unsafe { ColorField { shift: read(tag + offset), size: read(tag + offset + 1) } };
+            FramebufferFormat::Rgb { red: field(32), green: field(34), blue: field(36) }
+        }
+        FRAMEBUFFER_INDEXED => FramebufferFormat::Indexed,
+        _ => FramebufferFormat::Text,
+    };
+    unsafe {
+        Framebuffer {
+            address: read(tag + 8),
+            pitch: read(tag + 16),
+            width: read(tag + 20),
+            height: read(tag + 24),
+            bpp: read(tag + 28),
+            format,
+        }
+    }
+}
+
+/// Gather the boot information at physical address `address`
+pub fn parse(address: u64) -> BootInfo {
+    let mut info = BootInfo::new(Protocol::Multiboot2, PHYS_MEM_OFFSET);
+    info.direct_map_end = DIRECT_MAP_SIZE;
+    let total_size = unsafe { read::<u32>(address) } as u64;
+    info.reserve(address, address + total_size);
+    info.reserve(core::ptr::addr_of!(__kernel_start) as u64, core::ptr::addr_of!(__kernel_end) as u64);
+
+    let mut offset = 8;
+    while offset + 8 <= total_size {
+        let tag = address + offset;
+        let (kind, size) = unsafe { (read::<u32>(tag), read::<u32>(tag + 4)) };
+        if kind == TAG_END || size < 8 {
+            break;
+        }
+        match kind {
+            TAG_CMDLINE => info.set_cmdline(unsafe { c_str(tag + 8, size - 8) }),
+            TAG_LOADER_NAME => info.set_loader(unsafe { c_str(tag + 8, size - 8) }),
+            TAG_MODULE if size >= 16 => {
+                let (start, end) = unsafe { (read::<u32>(tag + 8) as u64, read::<u32>(tag + 12) as u64) };
+                info.add_module(start, end.saturating_sub(start), unsafe { c_str(tag + 16, size - 16) });
+            }
+            TAG_MMAP if size >= 16 => unsafe { parse_mmap(&mut info, tag, size) },
+            TAG_FRAMEBUFFER if size >= 32 => info.framebuffer = Some(unsafe { parse_framebuffer(tag) }),
+            // The tags hold a copy of the RSDP; the ACPI 2.0 one wins
+            TAG_ACPI_OLD if info.rsdp.is_none() => info.rsdp = Some(tag + 8),
+            TAG_ACPI_NEW => info.rsdp = Some(tag + 8),
+            _ => {}
+        }
+        offset += (size as u64 + 7) & !7;
+    }
+    info
+}
+
+/// Called by multiboot2_entry.S in long mode
+#[no_mangle]
+extern "C" fn multiboot2_main(magic: u32, info: u32) -> ! {
+    if magic != BOOTLOADER_MAGIC {
+        panic!("Multiboot2 entry with magic {:#x}: not started by a Multiboot2 loader", magic);
+    }
+    super::set(parse(info as u64));
+    crate::kernel_main()
+}
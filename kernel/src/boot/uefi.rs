@@ -0,0 +1,69 @@
+//! The project's UEFI loader (bootloader/)
+//!
+//! The loader reads the kernel from the EFI system partition, checks its
+//! signature, exits boot services and jumps to `uefi_start` at the kernel's
+//! higher-half link address (linker/uefi.ld) with a pointer to its boot
+//! information in the direct map. The structure's layout is shared with
+//! the loader by including its definition.
+
+#[path = "../../../bootloader/src/handoff.rs"]
+mod handoff;
+
+use super::{BootInfo, ColorField, Framebuffer, FramebufferFormat, MemoryRegionType, Protocol};
+
+fn color_field(mask: u32) -> ColorField {
+    ColorField { shift: mask.trailing_zeros() as u8, size: mask.count_ones() as u8 }
+}
+
+fn parse(loader: &handoff::BootInfo) -> BootInfo {
+    if loader.magic != handoff::MAGIC || loader.version != handoff::VERSION {
+        panic!("UEFI loader boot information {:#x} version {}: not what this kernel was built for", loader.magic, loader.version);
+    }
+    let mut info = BootInfo::new(Protocol::Uefi, loader.phys_offset);
+    info.set_loader(b"rust_bootloader");
+    info.direct_map_end = loader.direct_map_size;
+
+    for region in loader.memory_map() {
+        let region_type = match region.kind {
+            handoff::RegionKind::Usable => MemoryRegionType::Usable,
+            handoff::RegionKind::Reserved => MemoryRegionType::Reserved,
+            handoff::RegionKind::AcpiReclaimable => MemoryRegionType::AcpiReclaimable,
+            handoff::RegionKind::AcpiNvs => MemoryRegionType::AcpiNvs,
+            handoff::RegionKind::BadMemory => MemoryRegionType::BadMemory,
+            handoff::RegionKind::Loader => MemoryRegionType::Bootloader,
+            handoff::RegionKind::Kernel => MemoryRegionType::Kernel,
+        };
+        info.add_region(region.start, region.end, region_type);
+    }
+
+    let framebuffer = &loader.framebuffer;
+    if framebuffer.address != 0 {
+        info.framebuffer = Some(Framebuffer {
+            address: framebuffer.address,
+            width: framebuffer.width,
+            height: framebuffer.height,
+            pitch: framebuffer.stride * framebuffer.bpp.div_ceil(8),
+            bpp: framebuffer.bpp as u8,
+            format: FramebufferFormat::Rgb {
+                red: color_field(framebuffer.red_mask),
+                green: color_field(framebuffer.green_mask),
+                blue: color_field(framebuffer.blue_mask),
+            },
+        });
+    }
+
+    info.rsdp = Some(loader.rsdp).filter(|&rsdp| rsdp != 0);
+    info.secure_boot = loader.secure_boot == handoff::SecureBoot::Enforced;
+    info.set_cmdline(loader.cmdline());
+    for module in loader.modules() {
+        info.add_module(module.start, module.size, module.name());
+    }
+    info
+}
+
+/// The loader's jump target, named by linker/uefi.ld
+#[no_mangle]
+extern "C" fn uefi_start(loader: &'static handoff::BootInfo) -> ! {
+    super::set(parse(loader));
+    crate::kernel_main()
+}
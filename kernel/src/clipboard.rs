@@ -0,0 +1,293 @@
+// The system clipboard
+//
+// One clipboard for the whole system, shared by the shell, Win32 consoles,
+// Win32 programs and whatever in the kernel wants it. It holds data in one
+// or more formats, each kept as the bytes a Win32 program would find in
+// the global memory block: text as NUL-terminated CRLF text, bitmaps as a
+// device-independent bitmap. The text formats stand in for one another, as
+// Windows synthesizes them, so text put in one can be read in any.
+//
+// Each change bumps a sequence number, and the functions listening are
+// called with it once the clipboard is unlocked again.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+pub const CF_TEXT: u32 = 1;
+pub const CF_BITMAP: u32 = 2;
+pub const CF_OEMTEXT: u32 = 7;
+pub const CF_DIB: u32 = 8;
+pub const CF_UNICODETEXT: u32 = 13;
+
+// RegisterClipboardFormat hands out numbers from here
+const FIRST_REGISTERED_FORMAT: u32 = 0xC000;
+const LAST_REGISTERED_FORMAT: u32 = 0xFFFF;
+
+const BITMAPINFOHEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+// Text in the order the synthesized formats are made from
+const TEXT_FORMATS: [u32; 3] = [CF_UNICODETEXT, CF_TEXT, CF_OEMTEXT];
+
+struct Clipboard {
+    data: BTreeMap<u32, Vec<u8>>,
+    sequence: u32,
+    registered: Vec<String>,
+}
+
+static CLIPBOARD: Mutex<Clipboard> =
+    Mutex::new(Clipboard { data: BTreeMap::new(), sequence: 0, registered: Vec::new() });
+static LISTENERS: Mutex<Vec<fn(u32)>> = Mutex::new(Vec::new());
+
+/// An image, row after row from the top, as 0xAARRGGBB
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+/// Call `listener` with the new sequence number after each change
+pub fn add_listener(listener: fn(u32)) {
+    let mut listeners = LISTENERS.lock();
+    if !listeners.iter().any(|&l| l as usize == listener as usize) {
+        listeners.push(listener);
+    }
+}
+
+// Run with the clipboard locked, then tell the listeners
+fn change(update: impl FnOnce(&mut BTreeMap<u32, Vec<u8>>)) {
+    let sequence = {
+        let mut clipboard = CLIPBOARD.lock();
+        update(&mut clipboard.data);
+        clipboard.sequence = clipboard.sequence.wrapping_add(1);
+        clipboard.sequence
+    };
+    let listeners = LISTENERS.lock().clone();
+    for listener in listeners {
+        listener(sequence);
+    }
+}
+
+/// Replace everything on the clipboard with these formats, as one change
+pub fn replace(formats: Vec<(u32, Vec<u8>)>) {
+    change(|data| {
+        data.clear();
+        data.extend(formats);
+    });
+}
+
+/// Add formats to what is on the clipboard, replacing those it has, as
+/// one change
+pub fn extend(formats: Vec<(u32, Vec<u8>)>) {
+    change(|data| data.extend(formats));
+}
+
+pub fn empty() {
+    change(|data| data.clear());
+}
+
+/// Bumped by every change, so a reader can tell whether it has seen it
+pub fn sequence() -> u32 {
+    CLIPBOARD.lock().sequence
+}
+
+/// A format's bytes, synthesizing text formats from one another
+pub fn get(format: u32) -> Option<Vec<u8>> {
+    let clipboard = CLIPBOARD.lock();
+    if let Some(bytes) = clipboard.data.get(&format) {
+        return Some(bytes.clone());
+    }
+    if !TEXT_FORMATS.contains(&format) {
+        return None;
+    }
+    let (&from, bytes) = TEXT_FORMATS.iter().find_map(|f| clipboard.data.get_key_value(f))?;
+    Some(encode_text(&decode_text(from, bytes), format))
+}
+
+pub fn available(format: u32) -> bool {
+    let clipboard = CLIPBOARD.lock();
+    clipboard.data.contains_key(&format)
+        || TEXT_FORMATS.contains(&format) && TEXT_FORMATS.iter().any(|f| clipboard.data.contains_key(f))
+}
+
+/// The formats on the clipboard, those put there first, then the text
+/// formats that can be made from them
+pub fn formats() -> Vec<u32> {
+    let clipboard = CLIPBOARD.lock();
+    let mut formats: Vec<u32> = clipboard.data.keys().copied().collect();
+    if TEXT_FORMATS.iter().any(|f| clipboard.data.contains_key(f)) {
+        formats.extend(TEXT_FORMATS.iter().filter(|f| !clipboard.data.contains_key(f)));
+    }
+    formats
+}
+
+/// The number of a named format, registering it the first time; names
+/// are not case-sensitive
+pub fn register_format(name: &str) -> Option<u32> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut clipboard = CLIPBOARD.lock();
+    let registered = &mut clipboard.registered;
+    let index = match registered.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+        Some(index) => index,
+        None if registered.len() <= (LAST_REGISTERED_FORMAT - FIRST_REGISTERED_FORMAT) as usize => {
+            registered.push(name.to_string());
+            registered.len() - 1
+        }
+        None => return None,
+    };
+    Some(FIRST_REGISTERED_FORMAT + index as u32)
+}
+
+/// A format's name: the registered name, or the predefined one
+pub fn format_name(format: u32) -> Option<String> {
+    let name = match format {
+        CF_TEXT => "CF_TEXT",
+        CF_BITMAP => "CF_BITMAP",
+        CF_OEMTEXT => "CF_OEMTEXT",
+        CF_DIB => "CF_DIB",
+        CF_UNICODETEXT => "CF_UNICODETEXT",
+        _ => {
+            let index = format.checked_sub(FIRST_REGISTERED_FORMAT)? as usize;
+            return CLIPBOARD.lock().registered.get(index).cloned();
+        }
+    };
+    Some(name.to_string())
+}
+
+/// Whether `format` came from `register_format`, rather than being one
+/// Windows predefines
+pub fn is_registered(format: u32) -> bool {
+    (FIRST_REGISTERED_FORMAT..=LAST_REGISTERED_FORMAT).contains(&format)
+}
+
+/// Put text on the clipboard in place of what was there
+pub fn set_text(text: &str) {
+    replace(alloc::vec![(CF_UNICODETEXT, encode_text(text, CF_UNICODETEXT))]);
+}
+
+/// The text on the clipboard, with line breaks as `\n`
+pub fn text() -> Option<String> {
+    let clipboard = CLIPBOARD.lock();
+    let (&format, bytes) = TEXT_FORMATS.iter().find_map(|f| clipboard.data.get_key_value(f))?;
+    Some(decode_text(format, bytes))
+}
+
+// Text as a program would find it in `format`: CRLF line breaks and a
+// terminating NUL. The 8-bit formats have a `?` for what they cannot hold.
+fn encode_text(text: &str, format: u32) -> Vec<u8> {
+    let text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+    match format {
+        CF_UNICODETEXT => text.encode_utf16().chain([0]).flat_map(u16::to_le_bytes).collect(),
+        CF_OEMTEXT => text.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).chain([0]).collect(),
+        _ => text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).chain([0]).collect(),
+    }
+}
+
+// Text up to its NUL, with `\n` line breaks
+fn decode_text(format: u32, bytes: &[u8]) -> String {
+    let text = if format == CF_UNICODETEXT {
+        let units: Vec<u16> = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).collect();
+        let end = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+        String::from_utf16_lossy(&units[..end])
+    } else {
+        bytes.iter().take_while(|&&byte| byte != 0).map(|&byte| byte as char).collect()
+    };
+    text.replace("\r\n", "\n")
+}
+
+/// Put an image on the clipboard in place of what was there
+pub fn set_image(image: &Image) {
+    replace(alloc::vec![(CF_DIB, encode_dib(image))]);
+}
+
+/// The image on the clipboard, if there is one it can read
+pub fn image() -> Option<Image> {
+    decode_dib(CLIPBOARD.lock().data.get(&CF_DIB)?)
+}
+
+/// An image as a 32-bit bottom-up DIB: BITMAPINFOHEADER, then the rows
+pub fn encode_dib(image: &Image) -> Vec<u8> {
+    let size = image.width * image.height * 4;
+    let mut dib = Vec::with_capacity(BITMAPINFOHEADER_SIZE + size);
+    dib.extend_from_slice(&(BITMAPINFOHEADER_SIZE as u32).to_le_bytes());
+    dib.extend_from_slice(&(image.width as i32).to_le_bytes());
+    dib.extend_from_slice(&(image.height as i32).to_le_bytes());
+    dib.extend_from_slice(&1u16.to_le_bytes());
+    dib.extend_from_slice(&32u16.to_le_bytes());
+    dib.extend_from_slice(&BI_RGB.to_le_bytes());
+    dib.extend_from_slice(&(size as u32).to_le_bytes());
+    // 96 dpi, and no palette
+    dib.extend_from_slice(&3780i32.to_le_bytes());
+    dib.extend_from_slice(&3780i32.to_le_bytes());
+    dib.extend_from_slice(&[0; 8]);
+    if image.width > 0 {
+        for row in image.pixels.chunks(image.width).rev() {
+            dib.extend(row.iter().flat_map(|pixel| pixel.to_le_bytes()));
+        }
+    }
+    dib
+}
+
+/// A DIB of 1, 4, 8, 24 or 32 bits a pixel, uncompressed
+pub fn decode_dib(dib: &[u8]) -> Option<Image> {
+    let u16_at = |at: usize| dib.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| dib.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let header = u32_at(0)? as usize;
+    if header < BITMAPINFOHEADER_SIZE {
+        return None;
+    }
+    let width = u32_at(4)? as i32;
+    let height = u32_at(8)? as i32;
+    let bits = u16_at(14)? as usize;
+    let compression = u32_at(16)?;
+    let colors = u32_at(32)? as usize;
+    if width <= 0 || height == 0 || !matches!(bits, 1 | 4 | 8 | 24 | 32) {
+        return None;
+    }
+    // Bit fields are only taken as the usual masks, which follow the
+    // header when it is the short one
+    let mut table = header;
+    match compression {
+        BI_RGB => {}
+        BI_BITFIELDS if bits == 32 => {
+            if header == BITMAPINFOHEADER_SIZE {
+                table += 12;
+            }
+        }
+        _ => return None,
+    }
+    let palette_size = if bits <= 8 { if colors == 0 { 1 << bits } else { colors.min(1 << bits) } } else { 0 };
+    let palette: Vec<u32> = (0..palette_size).map(|i| u32_at(table + i * 4).unwrap_or(0) | 0xFF00_0000).collect();
+    let pixels_at = table + palette_size * 4;
+
+    let (width, bottom_up) = (width as usize, height > 0);
+    let height = height.unsigned_abs() as usize;
+    let stride = (width * bits).div_ceil(32) * 4;
+    let data = dib.get(pixels_at..pixels_at + stride * height)?;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if bottom_up { height - 1 - y } else { y };
+        let row = &data[row * stride..(row + 1) * stride];
+        for x in 0..width {
+            let pixel = match bits {
+                32 => u32::from_le_bytes([row[x * 4], row[x * 4 + 1], row[x * 4 + 2], 0]) | 0xFF00_0000,
+                24 => u32::from_le_bytes([row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 0]) | 0xFF00_0000,
+                _ => {
+                    let bit = x * bits;
+                    let index = (row[bit / 8] >> (8 - bits - bit % 8)) as usize & ((1 << bits) - 1);
+                    palette.get(index).copied().unwrap_or(0xFF00_0000)
+                }
+            };
+            pixels.push(pixel);
+        }
+    }
+    Some(Image { width, height, pixels })
+}
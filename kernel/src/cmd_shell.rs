@@ -1,22 +1,582 @@
-use alloc::string::String;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::format;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicI32, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
-use crate::{print, println, serial_println};
+use crate::serial_println;
+use crate::boot::cmdline::Param;
+use crate::fs::{vfs::VFS, FileSystemError};
+use crate::nt::object::Handle;
+use crate::power::shutdown::ShutdownAction;
+use crate::process::executor::EXECUTOR;
+use crate::security::accounts;
+
+mod edit;
+mod hexedit;
+mod screen;
+
+use editor::LineEditor;
+use screen::{App, Screen};
 
 const MAX_COMMAND_LENGTH: usize = 256;
-const COMMAND_HISTORY_SIZE: usize = 10;
+const PROMPT: &str = "ReactOS> ";
+
+// What Tab completes first on a line, besides files: the builtins and
+// batch statements
+const COMMANDS: &[&str] = &[
+    "audit", "bg", "bluetooth", "call", "camera", "cat", "checkpoint", "chkdsk", "clear", "clip", "clocksource", "cls",
+    "cmdline", "color", "cpu", "cpufreq", "cpuinfo", "crashdump", "date", "df", "dir", "dmesg", "echo", "edit", "ethtool",
+    "exec", "exit", "fan", "fg", "find", "findstr", "for", "gamepad", "goto", "groups", "heapcheck", "help", "hexdump",
+    "hexedit", "history", "hotkey", "http", "hwclock", "idle", "if", "input", "ionice", "jobs", "kprobe", "ksm",
+    "logoff", "logout", "ls", "lsdev", "lspci", "lsusb", "mem", "meminfo", "memory", "mkswap", "mount", "namespaces",
+    "oom", "paravirt", "passwd", "pcie", "pnp", "powercfg", "print", "printer", "processes", "profile", "ps", "rdp",
+    "reboot", "rem", "res", "restore", "run", "sandbox", "scan", "scanner", "serial", "set", "shift", "shutdown",
+    "sort", "swapoff", "swapon", "taskkill", "tasklist", "taskmgr", "taskset", "test", "thermal", "title", "trace", "type", "tz",
+    "umount", "uptime", "useradd", "userdel", "users", "ver", "version", "virt", "vnc", "watchdog", "wdm", "whoami",
+    "wifi",
+];
+
+static AUTOEXEC: Param<&str> = Param::new("shell.autoexec", "/autoexec.bat", "Batch file the shell runs at boot");
+
+// Batch lines run each time the main loop polls the shell, so that a
+// runaway script still lets Ctrl+C through
+const BATCH_LINES_PER_POLL: usize = 64;
+// Batch files calling batch files, and loops within them
+const MAX_BATCH_DEPTH: usize = 32;
+const MAX_LOOP_ITEMS: usize = 10_000;
+
+// Exit codes of builtins, after cmd.exe's where it has one
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ACCESS_DENIED: i32 = 5;
+const EXIT_NOT_FOUND: i32 = 9009;
+// A program ended by Ctrl+C, as the OOM killer's victims end with -9
+const EXIT_INTERRUPTED: i32 = -2;
+
+// Output of the command running goes to the console, or into this buffer
+// while a pipe or redirection is collecting it
+static CAPTURE: Mutex<Option<String>> = Mutex::new(None);
+
+// Exit code of the builtin running; 0 unless it reports a failure
+static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+
+fn write_output(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    let mut capture = CAPTURE.lock();
+    if let Some(buffer) = capture.as_mut() {
+        let _ = buffer.write_fmt(args);
+        return;
+    }
+    drop(capture);
+    // Through the console host, like a console program's output
+    let text = format!("{}", args);
+    if !crate::win32::console::write_shell(text.as_bytes()) {
+        crate::print!("{}", text);
+    }
+}
+
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::cmd_shell::write_output(format_args!($($arg)*)));
+}
+
+macro_rules! println {
+    () => (print!("\n"));
+    ($($arg:tt)*) => (print!("{}\n", format_args!($($arg)*)));
+}
+
+// The line editor echoes with the macros above, so it comes after them
+mod editor;
+
+// Report a builtin's failure
+macro_rules! fail {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        EXIT_CODE.store(EXIT_FAILURE, Ordering::Relaxed);
+    }};
+}
+
+fn usage(text: &str) {
+    println!("Usage: {}", text);
+    EXIT_CODE.store(EXIT_USAGE, Ordering::Relaxed);
+}
+
+fn access_denied(command: &str) {
+    println!("{}: access denied", command);
+    EXIT_CODE.store(EXIT_ACCESS_DENIED, Ordering::Relaxed);
+}
+
+// One word of a command line, or an operator between them
+#[derive(PartialEq)]
+enum Token {
+    Word(String),
+    Pipe,
+    Input,
+    Output,
+    Append,
+    Background,
+}
+
+// Split a command line into words and operators. Double quotes keep spaces
+// and operators in a word.
+fn tokenize(line: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let operator = match c {
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+                continue;
+            }
+            '|' => Some(Token::Pipe),
+            '<' => Some(Token::Input),
+            '>' if chars.peek() == Some(&'>') => {
+                chars.next();
+                Some(Token::Append)
+            }
+            '>' => Some(Token::Output),
+            '&' => Some(Token::Background),
+            c if c.is_whitespace() => None,
+            c => {
+                in_word = true;
+                word.push(c);
+                continue;
+            }
+        };
+        if in_word {
+            tokens.push(Token::Word(core::mem::take(&mut word)));
+            in_word = false;
+        }
+        tokens.extend(operator);
+    }
+    if in_word {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+// One command of a pipeline, with the files it reads and writes instead of
+// the pipe or console
+#[derive(Default)]
+struct Stage {
+    words: Vec<String>,
+    input: Option<String>,
+    // The file, and whether to append to it
+    output: Option<(String, bool)>,
+}
+
+// Group tokens into the stages of a pipeline; true with them if it runs in
+// the background
+fn parse(tokens: Vec<Token>) -> Result<(Vec<Stage>, bool), &'static str> {
+    let mut stages = alloc::vec![Stage::default()];
+    let mut background = false;
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if background {
+            return Err("'&' must end the line");
+        }
+        let stage = stages.last_mut().unwrap();
+        match token {
+            Token::Word(word) => stage.words.push(word),
+            Token::Pipe if stage.words.is_empty() => return Err("empty command in pipeline"),
+            Token::Pipe => stages.push(Stage::default()),
+            Token::Input => match tokens.next() {
+                Some(Token::Word(path)) => stage.input = Some(path),
+                _ => return Err("'<' needs a file name"),
+            },
+            Token::Output | Token::Append => match tokens.next() {
+                Some(Token::Word(path)) => stage.output = Some((path, token == Token::Append)),
+                _ => return Err("'>' needs a file name"),
+            },
+            Token::Background => background = true,
+        }
+    }
+    if stages.last().unwrap().words.is_empty() {
+        return Err("empty command");
+    }
+    Ok((stages, background))
+}
+
+// Commands not built into the shell: paths and .exe names
+fn is_program(name: &str) -> bool {
+    name.contains('/') || name.to_ascii_lowercase().ends_with(".exe")
+}
+
+fn read_text(path: &str) -> Result<String, FileSystemError> {
+    VFS.lock().read_file(path).map(|data| String::from_utf8_lossy(&data).into_owned())
+}
+
+fn write_text(path: &str, text: &str, append: bool) -> Result<(), FileSystemError> {
+    let mut vfs = VFS.lock();
+    let mut data = if append { vfs.read_file(path).unwrap_or_default() } else { Vec::new() };
+    data.extend_from_slice(text.as_bytes());
+    vfs.write_file(path, &data)
+}
+
+// Each user's history file, written with the user's own access like any
+// other file they create
+fn history_path(user: &str) -> String {
+    format!("/.history_{}", user.to_ascii_lowercase())
+}
+
+// A user's history, newest last. A file grown past the history's size is
+// cut back to it.
+fn load_history(user: &str) -> Vec<String> {
+    let path = history_path(user);
+    let Ok(text) = read_text(&path) else { return Vec::new() };
+    let mut lines: Vec<String> = text.lines().filter(|line| !line.is_empty()).map(String::from).collect();
+    if lines.len() > editor::HISTORY_SIZE {
+        lines.drain(..lines.len() - editor::HISTORY_SIZE);
+        let mut text = lines.join("\n");
+        text.push('\n');
+        let _ = write_text(&path, &text, false);
+    }
+    lines
+}
+
+// Commands run as batch files: .bat and .cmd names
+fn is_batch(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".bat") || name.ends_with(".cmd")
+}
+
+// The first word of `line`, quotes and all, and the text after it
+fn split_word(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    if line.is_empty() {
+        return None;
+    }
+    let mut quoted = false;
+    let end = line
+        .char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            !quoted && c.is_whitespace()
+        })
+        .map_or(line.len(), |(index, _)| index);
+    Some(line.split_at(end))
+}
+
+// The words of a line one at a time, leaving the rest of it as typed
+struct Words<'a>(&'a str);
+
+impl<'a> Iterator for Words<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (word, rest) = split_word(self.0)?;
+        self.0 = rest;
+        Some(word)
+    }
+}
+
+// End a process from outside, releasing what it holds as its own exit would
+fn end_process(pid: u32, exit_code: i32) {
+    crate::virt::ioctl::release_process(pid);
+    crate::serial::tty::release_process(pid);
+    crate::input::evdev::release_process(pid);
+    crate::container::release_process(pid);
+    crate::fs::file_ops::release_process(pid);
+    crate::memory::mmap::release_process(pid);
+    crate::process::fork::release_process(pid);
+    crate::drivers::block::release_process(pid);
+    EXECUTOR.lock().terminate_process(pid, exit_code);
+}
+
+// Take the system down, as an administrator only
+fn shut_down(command: &str, action: ShutdownAction) {
+    if !accounts::caller_is_admin() {
+        return access_denied(command);
+    }
+    match action {
+        ShutdownAction::PowerOff => println!("Shutting down..."),
+        ShutdownAction::Reboot => println!("Rebooting..."),
+    }
+    crate::power::shutdown::shutdown(action);
+}
+
+// A count with thousands separated by commas, as tasklist shows sizes
+fn thousands(n: u64) -> String {
+    let digits = format!("{}", n);
+    let mut text = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index) % 3 == 0 {
+            text.push(',');
+        }
+        text.push(digit);
+    }
+    text
+}
+
+// A size in bytes as df -h shows it, rounded up to the largest unit it fills
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["", "K", "M", "G", "T"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024 && unit + 1 < UNITS.len() {
+        value = value.div_ceil(1024);
+        unit += 1;
+    }
+    format!("{}{}", value, UNITS[unit])
+}
+
+// Millidegrees as degrees Celsius, to a tenth
+fn celsius(millidegrees: i32) -> String {
+    let sign = if millidegrees < 0 { "-" } else { "" };
+    let tenths = millidegrees.unsigned_abs() / 100;
+    format!("{}{}.{} C", sign, tenths / 10, tenths % 10)
+}
+
+// Whether `name` matches a pattern of * and ?, ignoring case as FAT does
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some((b'?', rest)), Some((_, name))) => glob_match(rest, name),
+        (Some((p, rest)), Some((n, name))) if p.eq_ignore_ascii_case(n) => glob_match(rest, name),
+        _ => false,
+    }
+}
+
+// A for loop's items: the words of its set, those with * or ? in them
+// replaced by the files they match
+fn for_items(set: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    for word in set.split(|c: char| c.is_whitespace() || c == ',').filter(|word| !word.is_empty()) {
+        if !word.contains(['*', '?']) {
+            items.push(String::from(word));
+            continue;
+        }
+        let (directory, prefix, pattern) = match word.rfind('/') {
+            Some(0) => ("/", "/", &word[1..]),
+            Some(slash) => (&word[..slash], &word[..=slash], &word[slash + 1..]),
+            None => ("/", "", word),
+        };
+        let Ok(files) = VFS.lock().list_directory(directory) else { continue };
+        for file in files {
+            if matches!(file.file_type, crate::fs::FileType::Regular) && glob_match(pattern.as_bytes(), file.name.as_bytes()) {
+                items.push(format!("{}{}", prefix, file.name));
+            }
+        }
+    }
+    items
+}
+
+// The commands a for loop runs, its variable replaced by each item in turn:
+// for [/l] %v in (set) do command
+fn for_lines(text: &str) -> Option<Vec<String>> {
+    let mut words = Words(text);
+    let mut variable = words.next()?;
+    let counting = variable.eq_ignore_ascii_case("/l");
+    if counting {
+        variable = words.next()?;
+    }
+    if variable.len() < 2 || !variable.starts_with('%') || !words.next()?.eq_ignore_ascii_case("in") {
+        return None;
+    }
+    let (set, rest) = words.0.trim_start().strip_prefix('(')?.split_once(')')?;
+    let mut words = Words(rest);
+    if !words.next()?.eq_ignore_ascii_case("do") || words.0.trim().is_empty() {
+        return None;
+    }
+    let command = words.0.trim();
+
+    let items: Vec<String> = if counting {
+        // (start,step,end), as cmd.exe counts
+        let numbers: Vec<i64> = set
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|word| !word.is_empty())
+            .map(|number| number.parse().ok())
+            .collect::<Option<_>>()?;
+        let [start, step, end] = numbers[..] else { return None };
+        if step == 0 {
+            return None;
+        }
+        (0..)
+            .map(|i| start + i * step)
+            .take_while(|&n| if step > 0 { n <= end } else { n >= end })
+            .take(MAX_LOOP_ITEMS + 1)
+            .map(|n| n.to_string())
+            .collect()
+    } else {
+        for_items(set)
+    };
+    if items.len() > MAX_LOOP_ITEMS {
+        return None;
+    }
+    Some(items.iter().map(|item| command.replace(variable, item)).collect())
+}
+
+// A `set /a` expression: integers and variables, with + - * / % and
+// parentheses
+struct Arithmetic<'a> {
+    text: &'a [u8],
+    position: usize,
+    variables: &'a BTreeMap<String, String>,
+}
+
+impl Arithmetic<'_> {
+    fn peek(&mut self) -> Option<u8> {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+        self.text.get(self.position).copied()
+    }
+
+    fn evaluate(mut self) -> Result<i64, &'static str> {
+        let value = self.sum()?;
+        match self.peek() {
+            None => Ok(value),
+            Some(_) => Err("bad expression"),
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, &'static str> {
+        let mut value = self.product()?;
+        while let Some(op @ (b'+' | b'-')) = self.peek() {
+            self.position += 1;
+            let operand = self.product()?;
+            value = if op == b'+' { value.wrapping_add(operand) } else { value.wrapping_sub(operand) };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, &'static str> {
+        let mut value = self.operand()?;
+        while let Some(op @ (b'*' | b'/' | b'%')) = self.peek() {
+            self.position += 1;
+            let operand = self.operand()?;
+            value = match op {
+                b'*' => value.wrapping_mul(operand),
+                _ if operand == 0 => return Err("division by zero"),
+                b'/' => value.wrapping_div(operand),
+                _ => value.wrapping_rem(operand),
+            };
+        }
+        Ok(value)
+    }
+
+    fn operand(&mut self) -> Result<i64, &'static str> {
+        match self.peek() {
+            Some(b'-') => {
+                self.position += 1;
+                Ok(self.operand()?.wrapping_neg())
+            }
+            Some(b'(') => {
+                self.position += 1;
+                let value = self.sum()?;
+                if self.peek() != Some(b')') {
+                    return Err("missing ')'");
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == b'_' => {
+                let start = self.position;
+                while self.text.get(self.position).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_') {
+                    self.position += 1;
+                }
+                let word = core::str::from_utf8(&self.text[start..self.position]).unwrap_or("");
+                if c.is_ascii_digit() {
+                    return word.parse().map_err(|_| "bad number");
+                }
+                // An unset or non-numeric variable counts as 0
+                Ok(self.variables.get(&word.to_ascii_uppercase()).and_then(|value| value.trim().parse().ok()).unwrap_or(0))
+            }
+            _ => Err("bad expression"),
+        }
+    }
+}
+
+// A batch file being run, or the commands a for loop expanded to
+struct Batch {
+    lines: Vec<String>,
+    // The next line to run
+    next: usize,
+    // %0 to %9 and beyond of a file, or of a call to one of its labels;
+    // None for a loop, whose lines were expanded when it started
+    args: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Running,
+    Stopped,
+}
+
+// A program the shell started
+struct Job {
+    id: usize,
+    pid: u32,
+    command: String,
+    state: JobState,
+}
+
+// Interactive logon progress of the shell
+enum LoginState {
+    SetupPassword,
+    SetupConfirm(String),
+    Username,
+    Password(String),
+    LoggedIn { user: String, token: Handle },
+}
 
 pub struct Shell {
-    command_buffer: String,
+    editor: LineEditor,
     cursor_visible: bool,
+    login: LoginState,
+    // Input of the builtin running: what the stage before it in a pipeline
+    // wrote, or the file given with `<`
+    stdin: Option<String>,
+    // Exit code of the last command line
+    status: i32,
+    jobs: Vec<Job>,
+    // The job whose end the prompt waits for
+    foreground: Option<usize>,
+    next_job: usize,
+    // Variables, by upper-case name
+    variables: BTreeMap<String, String>,
+    // Batch files and loops running, innermost last
+    batches: Vec<Batch>,
+    // Whether batch lines are shown as they run
+    echo: bool,
+    // A full-screen command running, which has the keyboard
+    screen: Option<Screen>,
 }
 
 impl Shell {
     pub fn new() -> Self {
         Self {
-            command_buffer: String::new(),
+            editor: LineEditor::new(),
             cursor_visible: true,
+            login: if accounts::needs_setup() {
+                LoginState::SetupPassword
+            } else {
+                LoginState::Username
+            },
+            stdin: None,
+            status: 0,
+            jobs: Vec::new(),
+            foreground: None,
+            next_job: 1,
+            variables: BTreeMap::new(),
+            batches: Vec::new(),
+            echo: true,
+            screen: None,
         }
     }
 
@@ -31,111 +591,3781 @@ impl Shell {
         println!("Type 'help' for available commands\n");
     }
 
+    fn prompt(&self) -> &'static str {
+        match &self.login {
+            LoginState::SetupPassword => "Administrator password: ",
+            LoginState::SetupConfirm(_) => "Confirm password: ",
+            LoginState::Username => "login: ",
+            LoginState::Password(_) => "Password: ",
+            LoginState::LoggedIn { .. } => PROMPT,
+        }
+    }
+
     fn print_prompt(&self) {
-        print!("ReactOS> ");
+        print!("{}", self.prompt());
+    }
+
+    fn masked_input(&self) -> bool {
+        matches!(
+            self.login,
+            LoginState::SetupPassword | LoginState::SetupConfirm(_) | LoginState::Password(_)
+        )
+    }
+
+    fn handle_login(&mut self, input: String) {
+        let state = core::mem::replace(&mut self.login, LoginState::Username);
+
+        self.login = match state {
+            LoginState::SetupPassword => {
+                if input.is_empty() {
+                    println!("The Administrator password cannot be empty.");
+                    LoginState::SetupPassword
+                } else {
+                    LoginState::SetupConfirm(input)
+                }
+            }
+            LoginState::SetupConfirm(first) => {
+                if first != input {
+                    println!("Passwords do not match.");
+                    LoginState::SetupPassword
+                } else if let Err(status) = accounts::setup_administrator(&input) {
+                    println!("Failed to set the Administrator password: {:?}", status);
+                    LoginState::SetupPassword
+                } else {
+                    println!("Administrator password set. Log in as Administrator.");
+                    LoginState::Username
+                }
+            }
+            LoginState::Username => {
+                let user = input.trim();
+                if user.is_empty() {
+                    LoginState::Username
+                } else {
+                    LoginState::Password(String::from(user))
+                }
+            }
+            LoginState::Password(user) => match accounts::logon_user(&user, &input) {
+                Ok(token) => {
+                    attach_token(token);
+                    let user = accounts::token_user_name(token).unwrap_or(user);
+                    println!("Welcome, {}.", user);
+                    self.editor.set_history(load_history(&user));
+                    LoginState::LoggedIn { user, token }
+                }
+                Err(_) => {
+                    println!("Logon failure: unknown user name or bad password.");
+                    LoginState::Username
+                }
+            },
+            logged_in @ LoginState::LoggedIn { .. } => logged_in,
+        };
     }
 
     pub fn handle_key(&mut self, key: char) {
-        match key {
-            '\n' => {
-                println!(); // New line after command
-                self.execute_command();
-                self.command_buffer.clear();
-                self.print_prompt();
+        if let Some(screen) = self.screen.as_mut() {
+            if !screen.key(key) {
+                self.screen.take().unwrap().close();
+                self.run_batches();
+                if !self.busy() {
+                    self.print_prompt();
+                }
+            }
+            return;
+        }
+        // A foreground program or a batch file has the console; the shell
+        // only listens for Ctrl+C and Ctrl+Z
+        if self.busy() {
+            match key {
+                '\x03' => self.interrupt(),
+                '\x1a' => self.stop_foreground(),
+                _ => {}
             }
-            '\x08' => { // Backspace
-                if !self.command_buffer.is_empty() {
-                    self.command_buffer.pop();
-                    // Move cursor back, print space, move back again
-                    print!("\x08 \x08");
+            return;
+        }
+        if key == '\x03' {
+            // Ctrl+C with no program running abandons the line
+            println!("^C");
+            self.editor.clear();
+            self.print_prompt();
+            return;
+        }
+        let Some(line) = self.editor.key(key, self.prompt(), self.masked_input(), COMMANDS) else {
+            return;
+        };
+        println!(); // New line after command
+        if let LoginState::LoggedIn { .. } = self.login {
+            self.remember(&line);
+            self.execute_command(&line);
+        } else {
+            self.handle_login(line);
+        }
+        self.editor.clear();
+        self.run_batches();
+        if !self.busy() {
+            self.print_prompt();
+        }
+    }
+
+    // Add a line typed at the prompt to the history, and to the user's
+    // history file. Lines starting with a space are left out, as are those
+    // of commands that take passwords.
+    fn remember(&mut self, line: &str) {
+        let LoginState::LoggedIn { user, .. } = &self.login else { return };
+        let words: Vec<String> = line.split_whitespace().take(2).map(str::to_ascii_lowercase).collect();
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let takes_password = matches!(words[..], ["useradd", ..] | ["passwd", ..] | ["vnc", "password"]);
+        if line.trim().is_empty() || line.starts_with(' ') || takes_password {
+            return;
+        }
+        let path = history_path(user);
+        let line = line.trim_end();
+        if self.editor.history().last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.editor.remember(line);
+        let _ = write_text(&path, &format!("{}\n", line), true);
+    }
+
+    fn busy(&self) -> bool {
+        self.foreground.is_some() || !self.batches.is_empty() || self.screen.is_some()
+    }
+
+    fn execute_command(&mut self, command_line: &str) {
+        let command = command_line.trim();
+        
+        if command.is_empty() {
+            return;
+        }
+
+        let line = self.expand(command);
+        self.run_line(&line);
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        let name = name.to_ascii_uppercase();
+        self.variables.get(&name).cloned().or_else(|| (name == "ERRORLEVEL").then(|| self.status.to_string()))
+    }
+
+    // Replace %NAME% with the variable's value, and %ERRORLEVEL% and $? with
+    // the last exit code. In a batch file, %0 to %9 and %* are its arguments,
+    // %% is % and an unset variable is empty, as in cmd.exe; typed at the
+    // prompt, an unset one stays as it is.
+    fn expand(&self, line: &str) -> String {
+        let args = self.batches.last().and_then(|batch| batch.args.as_ref());
+        let mut expanded = String::new();
+        let mut rest = line;
+        while let Some(percent) = rest.find('%') {
+            expanded.push_str(&rest[..percent]);
+            rest = &rest[percent + 1..];
+            if let Some(args) = args {
+                let argument = match rest.chars().next() {
+                    Some('%') => Some(String::from("%")),
+                    Some(digit @ '0'..='9') => Some(args.get(digit as usize - '0' as usize).cloned().unwrap_or_default()),
+                    Some('*') => Some(args[1..].join(" ")),
+                    _ => None,
+                };
+                if let Some(argument) = argument {
+                    expanded.push_str(&argument);
+                    rest = &rest[1..];
+                    continue;
                 }
             }
-            '\x7f' => { // Delete (alternative backspace)
-                if !self.command_buffer.is_empty() {
-                    self.command_buffer.pop();
-                    print!("\x08 \x08");
+            match rest.find('%').map(|end| (&rest[..end], end)) {
+                Some((name, end)) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                    match self.variable(name) {
+                        Some(value) => expanded.push_str(&value),
+                        None if args.is_some() => {}
+                        None => {
+                            expanded.push('%');
+                            continue;
+                        }
+                    }
+                    rest = &rest[end + 1..];
                 }
+                _ => expanded.push('%'),
             }
-            _ => {
-                if self.command_buffer.len() < MAX_COMMAND_LENGTH && key.is_ascii() && !key.is_control() {
-                    self.command_buffer.push(key);
-                    print!("{}", key);
+        }
+        expanded.push_str(rest);
+        expanded.replace("$?", &self.status.to_string())
+    }
+
+    // Run one expanded line: a batch statement, or a pipeline
+    fn run_line(&mut self, line: &str) {
+        let Some((word, rest)) = split_word(line) else { return };
+        match word.to_ascii_lowercase().as_str() {
+            "if" => self.run_if(rest),
+            "for" => match for_lines(rest) {
+                Some(lines) => {
+                    self.push_batch(Batch { lines, next: 0, args: None });
+                }
+                None => self.statement_usage("for [/l] %v in (set) do command"),
+            },
+            "goto" => self.goto(rest.trim()),
+            "call" => self.call(rest),
+            "exit" => self.exit_batch(rest),
+            "shift" => {
+                if let Some(args) = self.batches.iter_mut().rev().find_map(|batch| batch.args.as_mut()) {
+                    if args.len() > 1 {
+                        args.remove(1);
+                    }
+                }
+            }
+            // Comments, and labels, which only goto looks at
+            "rem" => {}
+            _ if word.starts_with(':') => {}
+            _ => self.status = self.run_command(line.trim()),
+        }
+    }
+
+    fn statement_usage(&mut self, text: &str) {
+        usage(text);
+        self.status = EXIT_USAGE;
+    }
+
+    // if [/i] [not] errorlevel n | exist file | defined name | a==b command
+    fn run_if(&mut self, text: &str) {
+        const USAGE: &str = "if [/i] [not] errorlevel n | exist file | defined name | a==b command";
+        let mut words = Words(text);
+        let mut word = words.next();
+        let ignore_case = word.is_some_and(|word| word.eq_ignore_ascii_case("/i"));
+        if ignore_case {
+            word = words.next();
+        }
+        let negate = word.is_some_and(|word| word.eq_ignore_ascii_case("not"));
+        if negate {
+            word = words.next();
+        }
+        let condition = match word {
+            None => None,
+            Some(word) if word.eq_ignore_ascii_case("errorlevel") => {
+                words.next().and_then(|level| level.parse().ok()).map(|level: i32| self.status >= level)
+            }
+            Some(word) if word.eq_ignore_ascii_case("exist") => words.next().map(|path| {
+                let path = path.trim_matches('"');
+                let mut vfs = VFS.lock();
+                vfs.open(path, false).is_ok() || vfs.list_directory(path).is_ok()
+            }),
+            Some(word) if word.eq_ignore_ascii_case("defined") => words.next().map(|name| self.variable(name).is_some()),
+            Some(left) => {
+                let (left, right) = match left.split_once("==") {
+                    Some((left, "")) => (left, words.next()),
+                    Some((left, right)) => (left, Some(right)),
+                    None => match words.next() {
+                        Some("==") => (left, words.next()),
+                        Some(word) => (left, word.strip_prefix("==")),
+                        None => (left, None),
+                    },
+                };
+                right.map(|right| if ignore_case { left.eq_ignore_ascii_case(right) } else { left == right })
+            }
+        };
+        let command = words.0.trim();
+        match condition {
+            Some(_) if command.is_empty() => self.statement_usage(USAGE),
+            Some(condition) if condition != negate => self.run_line(command),
+            Some(_) => {}
+            None => self.statement_usage(USAGE),
+        }
+    }
+
+    fn push_batch(&mut self, batch: Batch) -> bool {
+        if self.batches.len() >= MAX_BATCH_DEPTH {
+            println!("Batch files nested too deeply");
+            self.status = EXIT_FAILURE;
+            return false;
+        }
+        self.batches.push(batch);
+        true
+    }
+
+    // Stop the innermost batch file, and the loops in it
+    fn leave_batch(&mut self) {
+        while let Some(batch) = self.batches.pop() {
+            if batch.args.is_some() {
+                break;
+            }
+        }
+    }
+
+    // Run a batch file. Run from another one without `call`, it takes that
+    // one's place, as in cmd.exe.
+    fn start_batch(&mut self, words: &[String], call: bool) -> i32 {
+        let lines = match read_text(words[0].trim_matches('"')) {
+            Ok(text) => text.lines().map(String::from).collect(),
+            Err(FileSystemError::NotFound | FileSystemError::FileNotFound) => {
+                println!("'{}' is not recognized as a command or program", words[0]);
+                return EXIT_NOT_FOUND;
+            }
+            Err(_) => {
+                println!("Error: Cannot read file '{}'", words[0]);
+                return EXIT_FAILURE;
+            }
+        };
+        if !call {
+            self.leave_batch();
+        }
+        if self.push_batch(Batch { lines, next: 0, args: Some(words.to_vec()) }) {
+            0
+        } else {
+            EXIT_FAILURE
+        }
+    }
+
+    // Continue the innermost batch file after its label; a goto leaves any
+    // loop it is in
+    fn goto(&mut self, label: &str) {
+        while self.batches.last().is_some_and(|batch| batch.args.is_none()) {
+            self.batches.pop();
+        }
+        let label = label.split_whitespace().next().unwrap_or("").trim_start_matches(':');
+        let Some(batch) = self.batches.last_mut() else {
+            println!("goto: not in a batch file");
+            self.status = EXIT_FAILURE;
+            return;
+        };
+        if label.eq_ignore_ascii_case("eof") {
+            batch.next = batch.lines.len();
+            return;
+        }
+        let target = batch.lines.iter().position(|line| {
+            line.trim_start()
+                .strip_prefix(':')
+                .and_then(|line| line.split_whitespace().next())
+                .is_some_and(|name| name.eq_ignore_ascii_case(label))
+        });
+        match target {
+            Some(index) => batch.next = index + 1,
+            None => {
+                println!("goto: no label '{}'", label);
+                self.status = EXIT_FAILURE;
+                self.leave_batch();
+            }
+        }
+    }
+
+    // call file [args]: run a batch file and come back. call :label [args]:
+    // run the current file from its label, until goto :eof or its end.
+    fn call(&mut self, text: &str) {
+        let words: Vec<String> = Words(text).map(String::from).collect();
+        let Some(target) = words.first() else {
+            return self.statement_usage("call file [args] | call :label [args]");
+        };
+        let Some(label) = target.strip_prefix(':') else {
+            self.status = self.start_batch(&words, true);
+            return;
+        };
+        let Some(lines) = self.batches.iter().rev().find(|batch| batch.args.is_some()).map(|batch| batch.lines.clone()) else {
+            println!("call: not in a batch file");
+            self.status = EXIT_FAILURE;
+            return;
+        };
+        let label = String::from(label);
+        if self.push_batch(Batch { lines, next: 0, args: Some(words) }) {
+            self.goto(&label);
+        }
+    }
+
+    // exit [/b] [code]: leave every batch file running, or with /b only the
+    // innermost
+    fn exit_batch(&mut self, text: &str) {
+        let mut words = text.split_whitespace().peekable();
+        let innermost = words.next_if(|word| word.eq_ignore_ascii_case("/b")).is_some();
+        if let Some(code) = words.next() {
+            match code.parse() {
+                Ok(code) => self.status = code,
+                Err(_) => return self.statement_usage("exit [/b] [code]"),
+            }
+        }
+        if innermost {
+            self.leave_batch();
+        } else {
+            self.batches.clear();
+        }
+    }
+
+    // Run queued batch lines until they run out, one starts a foreground
+    // program, or this pass's share is done; the rest run on later polls
+    fn run_batches(&mut self) {
+        for _ in 0..BATCH_LINES_PER_POLL {
+            if self.foreground.is_some() || self.screen.is_some() {
+                return;
+            }
+            let Some(batch) = self.batches.last_mut() else { return };
+            let Some(line) = batch.lines.get(batch.next).cloned() else {
+                self.batches.pop();
+                continue;
+            };
+            batch.next += 1;
+            if batch.args.is_none() {
+                self.run_line(&line);
+                continue;
+            }
+
+            // @ keeps one line quiet, as echo off does them all
+            let (quiet, line) = match line.trim_start().strip_prefix('@') {
+                Some(line) => (true, line),
+                None => (false, line.trim_start()),
+            };
+            if self.echo && !quiet && !line.trim().is_empty() && !line.starts_with(':') {
+                println!("{}{}", PROMPT, line.trim_end());
+            }
+            let line = self.expand(line);
+            self.run_line(&line);
+        }
+    }
+
+    // Parse and run a command line
+    fn run_command(&mut self, command: &str) -> i32 {
+        let (stages, background) = match tokenize(command).and_then(parse) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                println!("Syntax error: {}", error);
+                return EXIT_USAGE;
+            }
+        };
+        let command = command.trim_end_matches('&').trim_end();
+        if background {
+            self.run_background(command, &stages)
+        } else {
+            self.run_pipeline(command, &stages)
+        }
+    }
+
+    // Run a line's stages, each builtin's output the next one's input.
+    // Returns the last one's exit code.
+    fn run_pipeline(&mut self, command: &str, stages: &[Stage]) -> i32 {
+        if let [Stage { words, input: None, output: None }] = stages {
+            if is_batch(&words[0]) {
+                return self.start_batch(words, false);
+            }
+            if is_program(&words[0]) {
+                return self.start_program(command, words, false);
+            }
+        }
+        let mut input = None;
+        let mut status = 0;
+        for (index, stage) in stages.iter().enumerate() {
+            if is_batch(&stage.words[0]) || is_program(&stage.words[0]) {
+                println!("{}: programs and batch files cannot be piped or redirected yet", stage.words[0]);
+                return EXIT_FAILURE;
+            }
+            if let Some(path) = &stage.input {
+                match read_text(path) {
+                    Ok(text) => input = Some(text),
+                    Err(_) => {
+                        println!("Error: Cannot read file '{}'", path);
+                        return EXIT_FAILURE;
+                    }
+                }
+            }
+            let capture = index + 1 < stages.len() || stage.output.is_some();
+            if capture {
+                *CAPTURE.lock() = Some(String::new());
+            }
+            self.stdin = input.take();
+            let parts: Vec<&str> = stage.words.iter().map(String::as_str).collect();
+            status = self.run_builtin(&parts);
+            self.stdin = None;
+            let output = if capture { CAPTURE.lock().take() } else { None };
+
+            // A stage whose output went to a file passes nothing on
+            if let (Some((path, append)), Some(text)) = (&stage.output, &output) {
+                if write_text(path, text, *append).is_err() {
+                    println!("Error: Cannot write file '{}'", path);
+                    return EXIT_FAILURE;
+                }
+            } else {
+                input = output;
+            }
+        }
+        status
+    }
+
+    // Only programs run in the background; a builtin would hold the shell
+    // until it finished anyway
+    fn run_background(&mut self, command: &str, stages: &[Stage]) -> i32 {
+        match stages {
+            [Stage { words, input: None, output: None }] if is_program(&words[0]) && !is_batch(&words[0]) => {
+                self.start_program(command, words, true)
+            }
+            _ => {
+                println!("Only a single program can run in the background");
+                EXIT_USAGE
+            }
+        }
+    }
+
+    fn start_program(&mut self, command: &str, words: &[String], background: bool) -> i32 {
+        use crate::process::fork::{self, SpawnError};
+
+        match fork::spawn(None, &words[0], words.join(" "), false) {
+            Ok(pid) => {
+                let id = self.next_job;
+                self.next_job += 1;
+                self.jobs.push(Job { id, pid, command: String::from(command), state: JobState::Running });
+                if background {
+                    println!("[{}] {}", id, pid);
+                } else {
+                    self.foreground = Some(id);
+                }
+                0
+            }
+            Err(SpawnError::NotFound) => {
+                println!("'{}' is not recognized as a command or program", words[0]);
+                EXIT_NOT_FOUND
+            }
+            Err(error) => {
+                println!("{}: {:?}", words[0], error);
+                EXIT_FAILURE
+            }
+        }
+    }
+
+    fn run_builtin(&mut self, parts: &[&str]) -> i32 {
+        EXIT_CODE.store(0, Ordering::Relaxed);
+        match parts[0] {
+            "help" => self.cmd_help(),
+            "clear" | "cls" => self.cmd_clear(),
+            "clip" => self.cmd_clip(&parts[1..]),
+            "res" => self.cmd_res(&parts[1..]),
+            "echo" => self.cmd_echo(&parts[1..]),
+            "color" => self.cmd_color(&parts[1..]),
+            "title" => self.cmd_title(&parts[1..]),
+            "set" => self.cmd_set(&parts[1..]),
+            "ver" | "version" => self.cmd_version(),
+            "mem" | "memory" => self.cmd_memory(),
+            "ps" | "processes" | "taskmgr" => self.cmd_processes(),
+            "tasklist" => self.cmd_tasklist(&parts[1..]),
+            "taskkill" => self.cmd_taskkill(&parts[1..]),
+            "meminfo" => self.cmd_meminfo(),
+            "cpuinfo" => self.cmd_cpuinfo(),
+            "lspci" => self.cmd_lspci(),
+            "lsusb" => self.cmd_lsusb(),
+            "ethtool" => self.cmd_ethtool(&parts[1..]),
+            "df" => self.cmd_df(&parts[1..]),
+            "chkdsk" => self.cmd_chkdsk(&parts[1..]),
+            "uptime" => self.cmd_uptime(),
+            "date" => self.cmd_date(&parts[1..]),
+            "tz" => self.cmd_tz(&parts[1..]),
+            "hwclock" => self.cmd_hwclock(&parts[1..]),
+            "ls" | "dir" => self.cmd_ls(&parts[1..]),
+            "cat" | "type" => self.cmd_cat(&parts[1..]),
+            "shutdown" => self.cmd_shutdown(&parts[1..]),
+            "reboot" => self.cmd_reboot(),
+            "test" => self.cmd_test(),
+            "exec" | "run" => self.cmd_execute(&parts[1..]),
+            "sandbox" => self.cmd_sandbox(&parts[1..]),
+            "whoami" => self.cmd_whoami(),
+            "logout" | "logoff" => self.cmd_logout(),
+            "useradd" => self.cmd_useradd(&parts[1..]),
+            "userdel" => self.cmd_userdel(&parts[1..]),
+            "passwd" => self.cmd_passwd(&parts[1..]),
+            "users" => self.cmd_users(),
+            "audit" => self.cmd_audit(&parts[1..]),
+            "crashdump" => self.cmd_crashdump(&parts[1..]),
+            "trace" => self.cmd_trace(&parts[1..]),
+            "kprobe" => self.cmd_kprobe(&parts[1..]),
+            "heapcheck" => self.cmd_heapcheck(&parts[1..]),
+            "profile" => self.cmd_profile(&parts[1..]),
+            "watchdog" => self.cmd_watchdog(&parts[1..]),
+            "virt" => self.cmd_virt(),
+            "paravirt" => self.cmd_paravirt(),
+            "namespaces" => self.cmd_namespaces(),
+            "groups" => self.cmd_groups(),
+            "checkpoint" => self.cmd_checkpoint(&parts[1..]),
+            "restore" => self.cmd_restore(&parts[1..]),
+            "clocksource" => self.cmd_clocksource(&parts[1..]),
+            "idle" => self.cmd_idle(&parts[1..]),
+            "cpu" => self.cmd_cpu(&parts[1..]),
+            "thermal" => self.cmd_thermal(&parts[1..]),
+            "fan" => self.cmd_fan(&parts[1..]),
+            "cpufreq" => self.cmd_cpufreq(&parts[1..]),
+            "powercfg" => self.cmd_powercfg(&parts[1..]),
+            "input" => self.cmd_input(&parts[1..]),
+            "hotkey" => self.cmd_hotkey(&parts[1..]),
+            "gamepad" => self.cmd_gamepad(&parts[1..]),
+            "camera" => self.cmd_camera(&parts[1..]),
+            "bluetooth" => self.cmd_bluetooth(&parts[1..]),
+            "wifi" => self.cmd_wifi(&parts[1..]),
+            "print" => self.cmd_print(&parts[1..]),
+            "printer" => self.cmd_printer(&parts[1..]),
+            "scan" => self.cmd_scan(&parts[1..]),
+            "scanner" => self.cmd_scanner(&parts[1..]),
+            "vnc" => self.cmd_vnc(&parts[1..]),
+            "rdp" => self.cmd_rdp(&parts[1..]),
+            "http" => self.cmd_http(&parts[1..]),
+            "mount" => self.cmd_mount(&parts[1..]),
+            "umount" => self.cmd_umount(&parts[1..]),
+            "taskset" => self.cmd_taskset(&parts[1..]),
+            "ionice" => self.cmd_ionice(&parts[1..]),
+            "serial" => self.cmd_serial(&parts[1..]),
+            "dmesg" => self.cmd_dmesg(&parts[1..]),
+            "cmdline" => self.cmd_cmdline(&parts[1..]),
+            "lsdev" => self.cmd_lsdev(&parts[1..]),
+            "pcie" => self.cmd_pcie(&parts[1..]),
+            "pnp" => self.cmd_pnp(&parts[1..]),
+            "ksm" => self.cmd_ksm(&parts[1..]),
+            "wdm" => self.cmd_wdm(&parts[1..]),
+            "mkswap" => self.cmd_mkswap(&parts[1..]),
+            "swapon" => self.cmd_swapon(&parts[1..]),
+            "swapoff" => self.cmd_swapoff(&parts[1..]),
+            "oom" => self.cmd_oom(&parts[1..]),
+            "find" | "findstr" => self.cmd_find(&parts[1..]),
+            "sort" => self.cmd_sort(&parts[1..]),
+            "edit" => self.cmd_edit(&parts[1..]),
+            "hexedit" => self.cmd_hexedit(&parts[1..]),
+            "hexdump" => self.cmd_hexdump(&parts[1..]),
+            "jobs" => self.cmd_jobs(),
+            "history" => self.cmd_history(&parts[1..]),
+            "fg" => self.cmd_fg(&parts[1..]),
+            "bg" => self.cmd_bg(&parts[1..]),
+            _ => {
+                println!("Unknown command: '{}'. Type 'help' for available commands.", parts[0]);
+                EXIT_CODE.store(EXIT_NOT_FOUND, Ordering::Relaxed);
+            }
+        }
+        EXIT_CODE.load(Ordering::Relaxed)
+    }
+
+    // The job numbered `args[0]`, or the most recent
+    fn job(&self, args: &[&str]) -> Option<usize> {
+        let index = match args {
+            [] => self.jobs.len().checked_sub(1),
+            [id] => {
+                let id = id.trim_start_matches('%').parse().ok()?;
+                self.jobs.iter().position(|job| job.id == id)
+            }
+            _ => None,
+        };
+        if index.is_none() {
+            fail!("{}: no such job", args.first().unwrap_or(&"current"));
+        }
+        index
+    }
+
+    fn cmd_jobs(&self) {
+        for job in &self.jobs {
+            println!("[{}] {:<8} {:>5}  {}", job.id, format!("{:?}", job.state), job.pid, job.command);
+        }
+    }
+
+    fn cmd_history(&mut self, args: &[&str]) {
+        match args {
+            [] => {
+                for (number, line) in self.editor.history().iter().enumerate() {
+                    println!("{:>5}  {}", number + 1, line);
+                }
+            }
+            [clear] if clear.eq_ignore_ascii_case("/c") => {
+                self.editor.set_history(Vec::new());
+                if let LoginState::LoggedIn { user, .. } = &self.login {
+                    if write_text(&history_path(user), "", false).is_err() {
+                        fail!("history: cannot clear the history file");
+                    }
+                }
+            }
+            _ => usage("history [/c]"),
+        }
+    }
+
+    fn cmd_fg(&mut self, args: &[&str]) {
+        let Some(index) = self.job(args) else { return };
+        let job = &mut self.jobs[index];
+        if job.state == JobState::Stopped {
+            EXECUTOR.lock().resume_process(job.pid);
+            job.state = JobState::Running;
+        }
+        println!("{}", job.command);
+        self.foreground = Some(job.id);
+    }
+
+    fn cmd_bg(&mut self, args: &[&str]) {
+        let Some(index) = self.job(args) else { return };
+        let job = &mut self.jobs[index];
+        if job.state == JobState::Running {
+            fail!("bg: job {} is already running", job.id);
+            return;
+        }
+        EXECUTOR.lock().resume_process(job.pid);
+        job.state = JobState::Running;
+        println!("[{}] {} &", job.id, job.command);
+    }
+
+    fn foreground_job(&mut self) -> Option<&mut Job> {
+        let id = self.foreground?;
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    // Ctrl+C: end the foreground program, as SIGINT's default action would,
+    // and any batch files running. There are no signals yet, so a program
+    // cannot catch it.
+    fn interrupt(&mut self) {
+        println!("^C");
+        if !self.batches.is_empty() {
+            self.batches.clear();
+            self.status = EXIT_INTERRUPTED;
+            println!("Batch job terminated");
+        }
+        let Some(pid) = self.foreground_job().map(|job| job.pid) else {
+            self.print_prompt();
+            return;
+        };
+        end_process(pid, EXIT_INTERRUPTED);
+        self.reap();
+    }
+
+    // Ctrl+Z: take the foreground program off the run queue and give the
+    // console back. One blocked at the time runs again once woken.
+    fn stop_foreground(&mut self) {
+        let Some(job) = self.foreground_job() else { return };
+        EXECUTOR.lock().suspend_process(job.pid);
+        job.state = JobState::Stopped;
+        let (id, command) = (job.id, job.command.clone());
+        self.foreground = None;
+        println!("^Z");
+        println!("[{}] Stopped  {}", id, command);
+        // A batch file goes on to its next line
+        if !self.busy() {
+            self.print_prompt();
+        }
+    }
+
+    // Drop the jobs whose programs have ended. The foreground job's exit
+    // code becomes the shell's and its prompt comes back; a background
+    // job's end is reported.
+    fn reap(&mut self) {
+        let mut finished = Vec::new();
+        {
+            let mut executor = EXECUTOR.lock();
+            self.jobs.retain(|job| {
+                if executor.get_process(job.pid).is_some() {
+                    return true;
+                }
+                finished.push((job.id, executor.take_exit_code(job.pid).unwrap_or(0), job.command.clone()));
+                false
+            });
+        }
+        for (id, code, command) in finished {
+            if self.foreground == Some(id) {
+                self.foreground = None;
+                self.status = code;
+                if !self.busy() {
+                    self.print_prompt();
+                }
+                continue;
+            }
+            match code {
+                0 => println!("\n[{}] Done  {}", id, command),
+                _ => println!("\n[{}] Exit {}  {}", id, code, command),
+            }
+            if !self.busy() {
+                let prompt = self.prompt();
+                let masked = self.masked_input();
+                self.editor.redisplay(prompt, masked);
+            }
+        }
+    }
+
+    fn cmd_help(&self) {
+        println!("Available commands:");
+        println!("  help          - Show this help message");
+        println!("  clear/cls     - Clear the screen");
+        println!("  clip [/l] [file] - Copy a file or piped text to the clipboard, or show what it holds");
+        println!("  echo [text]   - Print text to screen");
+        println!("  color [bf]    - Set the console's background and foreground colours, as hex digits");
+        println!("  title [text]  - Show or set the console's title");
+        println!("  ver/version   - Show system version");
+        println!("  mem/memory    - Show memory usage");
+        println!("  ps/taskmgr    - List processes with their I/O priority and I/O");
+        println!("  tasklist [/v] - Processes with their parent, state and memory; /v adds command lines");
+        println!("  taskkill /pid n | /im name [/t] [/f] - End processes, with /t their children too");
+        println!("  meminfo       - Physical memory, page cache, swap and kernel heap, as /proc/meminfo");
+        println!("  cpuinfo       - CPU model, caches and features, and each CPU's topology");
+        println!("  lspci         - PCI functions, their class, IDs and driver");
+        println!("  lsusb         - USB devices, their IDs, speed, class and driver");
+        println!("  ethtool [iface [coalesce usecs | rss n]] - NIC offloads and queues; set moderation or RSS");
+        println!("  df [-h]       - Mounted file systems and the space used on each");
+        println!("  uptime        - Show system uptime");
+        println!("  date [YYYY-MM-DD HH:MM[:SS] | /sync url] - Show or set local date and time");
+        println!("  tz [zone|TZ]  - Show or set the time zone (name or POSIX TZ rule)");
+        println!("  hwclock [local|utc] - Show the RTC; say whether it keeps local time");
+        println!("  ls/dir [path] - List directory contents");
+        println!("  cat/type [file] - Display file contents, or the input");
+        println!("  find text [file] - Lines containing text; fails if none");
+        println!("  sort [/r] [file] - Sort lines");
+        println!("  edit file     - Edit a text file, full screen");
+        println!("  hexedit file  - Edit a file's bytes, full screen");
+        println!("  hexdump [-s offset] [-n length] [file] - Show bytes in hex and text");
+        println!("  exec/run file - Execute a Windows .exe file");
+        println!("  res file [/v] - A program's or DLL's resources, or with /v its version");
+        println!("  sandbox profile name - Launch a process under a sandbox profile");
+        println!("  test          - Run system tests");
+        println!("  whoami        - Show the logged-on user");
+        println!("  logout        - End the current session");
+        println!("  users         - List local accounts");
+        println!("  useradd name password [/admin] - Create an account");
+        println!("  userdel name  - Delete an account");
+        println!("  passwd [name] old|- new - Change a password");
+        println!("  audit dump [severity] [count] | verify | level [severity] - Security audit log");
+        println!("  crashdump [type t | partition dev part|off | serial port|off | range addr size | clear-ranges | test]");
+        println!("  trace list | enable|disable event | start [KiB] | stop | clear | show [n] | save file | stats");
+        println!("  kprobe add function|0xaddr [name] | del name | list - Dynamic trace probes");
+        println!("  heapcheck mark | leaks [checkpoint] | verify | stats - Heap debugging (heap-debug builds)");
+        println!("  profile start [event] [period] | stop | report [n] | folded | save file - Sampling profiler");
+        println!("  watchdog [soft|hard secs|off] [panic|dump on|off] [test soft|hard] - Lockup detectors");
+        println!("  virt                 - Virtualization backend and running VMs");
+        println!("  paravirt             - Hypervisor enlightenments and VMBus channels");
+        println!("  namespaces           - PID, mount, network and UTS namespaces in use");
+        println!("  groups               - Resource groups, their limits and usage");
+        println!("  checkpoint pid file [stop] - Save a process to a file, stopping it with 'stop'");
+        println!("  restore file         - Start a process from a checkpoint");
+        println!("  clocksource [name]   - Clocksource, TSC and HPET state and pending hrtimers; switch source");
+        println!("  idle [latency_us]    - Idle states and per-CPU residency; limit exit latency");
+        println!("  cpu [online|offline n] - CPUs with user/kernel/irq/idle time; take one offline or back");
+        println!("  thermal [status | policy quiet|balanced|performance] - Temperatures, trip points and fans; set the policy");
+        println!("  fan [set id percent] - Fans and their speed; set one by hand");
+        println!("  cpufreq [governor [name]] - CPU frequency and governor; list or change governors");
+        println!("  powercfg /a | /hibernate on|off - Sleep states available; allow hibernation");
+        println!("  powercfg /devicequery wake_armed|wake_programmable - Devices that can wake the system");
+        println!("  powercfg /deviceenablewake|/devicedisablewake path - Let a device wake the system or not");
+        println!("  powercfg /lastwake - The device that woke the system last");
+        println!("  input [events [count]] - Touchpads, hotkeys and I2C buses; take queued input events");
+        println!("  hotkey [map query key | unmap query] - EC hotkeys, brightness, volume and radios; map a key");
+        println!("  gamepad [test player | rumble player low% high% [ms]] - Gamepads; read one through XInput");
+        println!("  camera [formats n | set n WxH [yuyv|nv12|mjpeg] | grab n file] - Webcams; save a frame");
+        println!("  bluetooth [scan [s] | devices | connect addr [pin] | disconnect|unpair addr | attach|detach n] - Bluetooth");
+        println!("  wifi [scan | networks | connect ssid [passphrase] | disconnect] - Wi-Fi networks; join or leave one");
+        println!("  print [/d:printer] [/p:priority] [/c:copies] file - Queue a PDF, PostScript or text file");
+        println!("  printer [jobs | cancel job | add name uri [lang] | pause|resume|default|remove name] - Printers");
+        println!("  scan [/d:scanner] [/r:dpi] [/m:mode] [/s:source] [/f:format] [dir] - Scan pages into a directory");
+        println!("  scanner [jobs | info name | cancel job | add name uri | remove name] - Scanners");
+        println!("  vnc [start [port] | stop | password text | disconnect n] - Remote display server and its clients");
+        println!("  rdp [start [port] | stop | disconnect n] - Remote desktop server for RDP clients");
+        println!("  http [get url file | cancel n | serve [port] | stop] - HTTP transfers and the /proc status server");
+        println!("  mount [9p source dir] - List mounts and 9P shares, or mount a share");
+        println!("  umount dir    - Take a file system off its mount point");
+        println!("  taskset tid [mask]   - A thread's CPU affinity mask, in hex; set it");
+        println!("  ionice pid [rt/n|be/n|idle] - A process's I/O priority; set it");
+        println!("  serial [n baud [rtscts|none]] - Serial ports, their owners and traffic; set speed");
+        println!("  dmesg [-c|-C] [-l level] [-n level] [count] - Kernel log; clear it, filter, set console level");
+        println!("  cmdline [-v]         - Kernel command line and the options in effect");
+        println!("  lsdev [-v]           - Device tree, with each device's driver or probe state");
+        println!("  pcie [slot n on|off] - Hot-plug slots and PCIe error counts; power a slot");
+        println!("  pnp [remove|disable|enable path] - Devices' resources; remove, disable or enable one");
+        println!("  ksm [on|off]         - Same-page merging counts; start or stop merging");
+        println!("  wdm [load file.sys | unload name] - Windows drivers and their devices; load or unload one");
+        println!("  chkdsk [diskN] [/f]  - Check a FAT32 or NTFS volume, the root one by default; /f repairs it");
+        println!("  mkswap target        - Write a swap header to diskN, diskNpM or a file");
+        println!("  swapon [target]      - Swap areas in use; start swapping to one");
+        println!("  swapoff target       - Read a swap area's pages back and stop using it");
+        println!("  oom [pid adj]        - OOM killer scores; set a process's adjustment (-1000 to 1000)");
+        println!("  set [name[=value]]   - Show or set variables; set /a name=expression does arithmetic");
+        println!("  if [not] errorlevel n|exist file|defined name|a==b command - Run a command conditionally");
+        println!("  for [/l] %v in (set) do command - Run a command for each item, file or number");
+        println!("  goto label | call file|:label [args] | exit [/b] [code] | shift - Batch file control");
+        println!("  history [/c]         - Lines typed at the prompt; clear them");
+        println!("  jobs                 - Programs started from this shell");
+        println!("  fg [n]               - Wait for a job, resuming it if stopped");
+        println!("  bg [n]               - Resume a stopped job in the background");
+        println!("  shutdown [/s|/r]     - End every process, flush the disks and power off or restart");
+        println!("  reboot               - Restart the system; the same as shutdown /r");
+        println!("\nRun programs by path or .exe name; 'program &' runs one in the background.");
+        println!("Ctrl+C ends the program running and Ctrl+Z stops it.");
+        println!("cmd | cmd pipes output, > file and >> file redirect it, < file reads input.");
+        println!("%name% is a variable; $? and %ERRORLEVEL% are the last exit code.");
+        println!("Run .bat and .cmd files by name; the shell runs /autoexec.bat at boot.");
+        println!("Arrows, Home and End move and recall lines; Ctrl+R searches them; Tab completes.");
+    }
+
+    fn cmd_whoami(&self) {
+        if let LoginState::LoggedIn { user, token } = &self.login {
+            let admin = crate::nt::security::SECURITY_MANAGER
+                .lock()
+                .get_token(*token)
+                .map_or(false, |token| token.is_admin());
+            println!("{}{}", user, if admin { " (Administrators)" } else { "" });
+        }
+    }
+
+    fn cmd_logout(&mut self) {
+        if let LoginState::LoggedIn { token, .. } = self.login {
+            attach_token(Handle::NULL);
+            accounts::logoff(token);
+        }
+        self.login = LoginState::Username;
+        self.editor.set_history(Vec::new());
+    }
+
+    fn cmd_users(&self) {
+        let database = accounts::ACCOUNTS.lock();
+        println!("  RID   | Name                 | Flags");
+        for account in database.accounts() {
+            println!(
+                "  {:<5} | {:<20} | {}{}",
+                account.rid,
+                account.name,
+                if account.is_admin() { "admin " } else { "" },
+                if account.is_disabled() { "disabled" } else { "" }
+            );
+        }
+    }
+
+    fn cmd_useradd(&self, args: &[&str]) {
+        if args.len() < 2 {
+            usage("useradd name password [/admin]");
+            return;
+        }
+        let admin = args.get(2).map_or(false, |flag| flag.eq_ignore_ascii_case("/admin"));
+        match accounts::create_account(args[0], args[1], admin) {
+            Ok(rid) => println!("Account {} created (RID {})", args[0], rid),
+            Err(status) => fail!("useradd: {:?}", status),
+        }
+    }
+
+    fn cmd_userdel(&self, args: &[&str]) {
+        if args.is_empty() {
+            usage("userdel name");
+            return;
+        }
+        match accounts::delete_account(args[0]) {
+            Ok(()) => println!("Account {} deleted", args[0]),
+            Err(status) => fail!("userdel: {:?}", status),
+        }
+    }
+
+    fn cmd_audit(&self, args: &[&str]) {
+        use crate::security::audit::Severity;
+        use crate::security::audit_log::{self, AuditQuery};
+
+        if !accounts::caller_is_admin() {
+            access_denied("audit");
+            return;
+        }
+        if !audit_log::is_enabled() {
+            fail!("audit: persistent log is not available");
+            return;
+        }
+
+        match args {
+            ["verify"] => {
+                let report = audit_log::verify();
+                println!(
+                    "Segments {}-{} ({}), {} records, anchored at {}",
+                    report.first_segment,
+                    report.last_segment,
+                    report.segments,
+                    report.records,
+                    &audit_log::hex(&report.anchor)[..16]
+                );
+                match report.error {
+                    None => println!("Chain intact"),
+                    Some(error) => println!("CHAIN BROKEN: {:?}", error),
+                }
+            }
+            ["level"] => println!("Persisting events at {:?} and above", audit_log::min_severity()),
+            ["level", name] => match Severity::from_name(name) {
+                Some(severity) => {
+                    audit_log::set_min_severity(severity);
+                    println!("Persisting events at {:?} and above", severity);
+                }
+                None => fail!("audit: unknown severity '{}'", name),
+            },
+            ["dump", rest @ ..] => {
+                let mut query = AuditQuery { limit: Some(20), ..AuditQuery::default() };
+                for arg in rest {
+                    if let Some(severity) = Severity::from_name(arg) {
+                        query.min_severity = Some(severity);
+                    } else if let Ok(count) = arg.parse() {
+                        query.limit = Some(count);
+                    }
+                }
+                for record in audit_log::query(&query) {
+                    println!(
+                        "#{} [{}] {:?} {} pid={} uid={}: {}",
+                        record.id, record.timestamp, record.severity, record.event, record.pid, record.uid, record.message
+                    );
+                    if !record.details.is_empty() {
+                        println!("    {}", record.details);
+                    }
+                }
+            }
+            _ => usage("audit dump [severity] [count] | verify | level [severity]"),
+        }
+    }
+
+    fn cmd_crashdump(&self, args: &[&str]) {
+        use crate::debug::kdump::{self, DumpType};
+
+        if !accounts::caller_is_admin() {
+            access_denied("crashdump");
+            return;
+        }
+
+        let number = |arg: &str| match arg.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => arg.parse().ok(),
+        };
+        match args {
+            [] => {
+                let config = kdump::CRASH_DUMP.config();
+                println!("Dump type: {:?}", config.dump_type);
+                match config.partition {
+                    Some((device, partition)) => println!("Partition: device {} partition {}", device, partition),
+                    None => println!("Partition: none"),
+                }
+                match config.serial_port {
+                    Some(port) => println!("Serial port: {:#x}", port),
+                    None => println!("Serial port: none"),
+                }
+                for (start, size) in &config.memory_ranges {
+                    println!("Memory: {:#x} +{:#x}", start, size);
+                }
+            }
+            ["type", name] => match DumpType::from_name(name) {
+                Some(dump_type) => kdump::set_dump_type(dump_type),
+                None => fail!("crashdump: types are mini, kernel, full and live"),
+            },
+            ["partition", "off"] => kdump::set_partition(None),
+            ["partition", device, partition] => match (device.parse(), partition.parse()) {
+                (Ok(device), Ok(partition)) => kdump::set_partition(Some((device, partition))),
+                _ => fail!("crashdump: bad device or partition number"),
+            },
+            ["serial", "off"] => kdump::set_serial_port(None),
+            ["serial", port] => match number(port) {
+                Some(port) if port <= 0xffff => kdump::set_serial_port(Some(port as u16)),
+                _ => fail!("crashdump: bad port"),
+            },
+            ["range", start, size] => match (number(start), number(size)) {
+                (Some(start), Some(size)) => kdump::add_memory_range(start, size),
+                _ => fail!("crashdump: bad range"),
+            },
+            ["clear-ranges"] => kdump::clear_memory_ranges(),
+            ["test"] => kdump::trigger_test_dump(),
+            _ => usage("crashdump [type t | partition dev part|off | serial port|off | range addr size | clear-ranges | test]"),
+        }
+    }
+
+    fn cmd_trace(&self, args: &[&str]) {
+        use crate::debug::trace;
+
+        if !accounts::caller_is_admin() {
+            access_denied("trace");
+            return;
+        }
+
+        match args {
+            [] => {
+                println!("Recording: {}", if trace::is_recording() { "on" } else { "off" });
+                println!("Buffer: {} KiB per CPU", trace::buffer_size() / 1024);
+                for event in trace::events().iter().filter(|event| event.enabled()) {
+                    println!("Enabled: {}:{}", event.system, event.name);
+                }
+            }
+            ["list"] => {
+                for event in trace::events() {
+                    println!("  {} {}:{} ({}) {} hits",
+                        if event.enabled() { "*" } else { " " },
+                        event.system,
+                        event.name,
+                        event.fields.join(", "),
+                        event.hits());
+                }
+            }
+            ["enable", pattern] | ["disable", pattern] => {
+                let enable = args[0] == "enable";
+                match trace::set_events_enabled(pattern, enable) {
+                    0 => fail!("trace: no events match '{}'", pattern),
+                    count => println!("{} {} event(s)", if enable { "Enabled" } else { "Disabled" }, count),
+                }
+            }
+            ["start"] => {
+                if let Err(error) = trace::start(trace::buffer_size()) {
+                    fail!("trace: {}", error);
+                }
+            }
+            ["start", size] => match size.parse::<usize>() {
+                Ok(kib) => {
+                    if let Err(error) = trace::start(kib * 1024) {
+                        fail!("trace: {}", error);
+                    }
+                }
+                Err(_) => fail!("trace: bad buffer size"),
+            },
+            ["stop"] => trace::stop(),
+            ["clear"] => trace::clear(),
+            ["show"] | ["show", _] => {
+                let count = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(50);
+                let events = trace::events();
+                let records = trace::records();
+                for record in &records[records.len().saturating_sub(count)..] {
+                    println!("{}", trace::format_record(record, &events));
+                }
+            }
+            ["save", path] => {
+                let data = trace::save();
+                match crate::fs::vfs::VFS.lock().write_file(path, &data) {
+                    Ok(()) => println!("Saved {} bytes to {}", data.len(), path),
+                    Err(_) => fail!("trace: cannot write '{}'", path),
+                }
+            }
+            ["stats"] => trace::print_statistics(),
+            _ => usage("trace list | enable|disable all|system[:event] | start [KiB] | stop | clear | show [n] | save file | stats"),
+        }
+    }
+
+    fn cmd_kprobe(&self, args: &[&str]) {
+        use crate::debug::kprobes;
+
+        if !accounts::caller_is_admin() {
+            access_denied("kprobe");
+            return;
+        }
+
+        match args {
+            ["add", target] | ["add", target, _] => match kprobes::add_probe(target, args.get(2).copied()) {
+                Ok(name) => println!("Armed probe kprobes:{}", name),
+                Err(error) => fail!("kprobe: {}", error),
+            },
+            ["del", name] => {
+                if let Err(error) = kprobes::remove_probe(name) {
+                    fail!("kprobe: {}", error);
+                }
+            }
+            ["list"] | [] => {
+                for (name, address, symbol, hits) in kprobes::list() {
+                    println!("  {:16} {:#018x} {} ({} hits)", name, address, symbol, hits);
+                }
+            }
+            _ => usage("kprobe add function|0xaddr [name] | del name | list"),
+        }
+    }
+
+    fn cmd_heapcheck(&self, args: &[&str]) {
+        use crate::allocator::heap_debug;
+
+        if !accounts::caller_is_admin() {
+            access_denied("heapcheck");
+            return;
+        }
+        if !heap_debug::enabled() {
+            fail!("heapcheck: heap debugging is not built in (make FEATURES=heap-debug)");
+            return;
+        }
+
+        match args {
+            ["mark"] => println!("Checkpoint #{}", heap_debug::checkpoint()),
+            ["leaks"] | ["leaks", _] => {
+                let since = match args.get(1) {
+                    Some(since) => match since.parse() {
+                        Ok(since) => since,
+                        Err(_) => {
+                            fail!("heapcheck: bad checkpoint");
+                            return;
+                        }
+                    },
+                    None => heap_debug::last_checkpoint(),
+                };
+                match heap_debug::report_leaks(since) {
+                    0 => println!("No leaks since checkpoint #{}", since),
+                    count => println!("{} object(s) leaked since checkpoint #{}; call sites on serial", count, since),
+                }
+            }
+            ["verify"] => match heap_debug::verify() {
+                0 => println!("Heap redzones and quarantine intact"),
+                count => println!("{} corrupted object(s); details on serial", count),
+            },
+            ["stats"] | [] => heap_debug::print_statistics(),
+            _ => usage("heapcheck mark | leaks [checkpoint] | verify | stats"),
+        }
+    }
+
+    fn cmd_profile(&self, args: &[&str]) {
+        use crate::perf::sampler::{self, SampleSource};
+
+        if !accounts::caller_is_admin() {
+            access_denied("profile");
+            return;
+        }
+
+        match args {
+            ["start"] | ["start", _] | ["start", _, _] => {
+                let source = match args.get(1) {
+                    Some(name) => match SampleSource::parse(name) {
+                        Some(source) => source,
+                        None => {
+                            fail!("profile: unknown event '{}' (cycles, instructions, cache-misses, timer)", name);
+                            return;
+                        }
+                    },
+                    None => SampleSource::Cycles,
+                };
+                let period = match args.get(2) {
+                    Some(period) => match period.parse() {
+                        Ok(period) => period,
+                        Err(_) => {
+                            fail!("profile: bad period");
+                            return;
+                        }
+                    },
+                    None => source.default_period(),
+                };
+                match sampler::start(source, period) {
+                    Ok(()) => println!("Sampling {} every {}", source.name(), period),
+                    Err(error) => fail!("profile: {}", error),
+                }
+            }
+            ["stop"] => sampler::stop(),
+            ["report"] | ["report", _] | [] => {
+                let limit = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(20);
+                sampler::print_report(limit);
+            }
+            ["folded"] => {
+                sampler::dump_folded();
+                println!("Folded stacks written to serial");
+            }
+            ["save", path] => {
+                let data = sampler::folded();
+                match crate::fs::vfs::VFS.lock().write_file(path, data.as_bytes()) {
+                    Ok(()) => println!("Saved {} bytes to {}", data.len(), path),
+                    Err(_) => fail!("profile: cannot write '{}'", path),
+                }
+            }
+            _ => usage("profile start [cycles|instructions|cache-misses|timer] [period] | stop | report [n] | folded | save file"),
+        }
+    }
+
+    fn cmd_watchdog(&self, args: &[&str]) {
+        use crate::debug::watchdog;
+
+        if !accounts::caller_is_admin() {
+            access_denied("watchdog");
+            return;
+        }
+
+        let on_off = |value: &str| match value {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+        let seconds = |value: &str| match value {
+            "off" => Some(0),
+            _ => value.parse::<u64>().ok(),
+        };
+
+        match args {
+            [] | ["status"] => {
+                let status = watchdog::status();
+                println!("Watchdog: {}", if status.enabled { "running" } else { "not started" });
+                match status.soft_threshold_secs {
+                    0 => println!("  Soft lockup: off"),
+                    secs => println!("  Soft lockup: {}s", secs),
+                }
+                match status.hard_mode {
+                    watchdog::HardLockupMode::Off => println!("  Hard lockup: off"),
+                    mode => println!("  Hard lockup: {}s ({})", status.hard_threshold_secs, mode.name()),
+                }
+                println!("  Panic on lockup: {}", if status.panic_on_lockup { "on" } else { "off" });
+                println!("  Dump on lockup: {}", if status.dump_on_lockup { "on" } else { "off" });
+                println!("  Detected: {} soft, {} hard", status.soft_lockups, status.hard_lockups);
+            }
+            ["soft", value] => match seconds(value) {
+                Some(secs) => watchdog::set_soft_threshold(secs),
+                None => fail!("watchdog: bad threshold '{}'", value),
+            },
+            ["hard", value] => match seconds(value) {
+                Some(secs) => watchdog::set_hard_threshold(secs),
+                None => fail!("watchdog: bad threshold '{}'", value),
+            },
+            ["panic", value] => match on_off(value) {
+                Some(enabled) => watchdog::set_panic_on_lockup(enabled),
+                None => usage("watchdog panic on|off"),
+            },
+            ["dump", value] => match on_off(value) {
+                Some(enabled) => watchdog::set_dump_on_lockup(enabled),
+                None => usage("watchdog dump on|off"),
+            },
+            ["test", "soft"] => watchdog::trigger_test_lockup(),
+            ["test", "hard"] => watchdog::trigger_test_hard_lockup(),
+            _ => usage("watchdog status | soft secs|off | hard secs|off | panic on|off | dump on|off | test soft|hard"),
+        }
+    }
+
+    fn cmd_virt(&self) {
+        if !accounts::caller_is_admin() {
+            access_denied("virt");
+            return;
+        }
+
+        match crate::virt::backend() {
+            Some(backend) => println!("Backend: {} ({})", backend.name(), crate::virt::ioctl::DEVICE_PATH),
+            None => {
+                println!("Backend: none");
+                return;
+            }
+        }
+        let vms = crate::virt::ioctl::vms();
+        if vms.is_empty() {
+            println!("No VMs");
+            return;
+        }
+        println!("  VM  PID  SLOTS  VCPUS");
+        for vm in vms {
+            println!("{:>4} {:>4} {:>6} {:>6}", vm.id, vm.owner, vm.slots, vm.vcpus);
+        }
+    }
+
+    fn cmd_paravirt(&self) {
+        use crate::paravirt::{self, hyperv, kvm, vmbus, Hypervisor};
+
+        let yes_no = |value: bool| if value { "yes" } else { "no" };
+        let Some(hypervisor) = paravirt::hypervisor() else {
+            println!("Hypervisor: none (bare metal)");
+            return;
+        };
+        println!("Hypervisor: {}", hypervisor.name());
+
+        match hypervisor {
+            Hypervisor::HyperV => {
+                let status = hyperv::status();
+                println!("  Hypercalls:       {}", yes_no(status.hypercalls));
+                println!("  Reference TSC:    {}", yes_no(status.reference_tsc));
+                println!("  SynIC:            {}", yes_no(status.synic));
+                println!("  Direct timers:    {}", yes_no(status.direct_timers));
+                println!("  Frequency MSRs:   {}", yes_no(status.frequency_msrs));
+            }
+            Hypervisor::Kvm => {
+                let status = kvm::status();
+                println!("  kvmclock:         {}{}", yes_no(status.kvmclock), if status.stable { " (stable)" } else { "" });
+                println!("  PV EOI:           {}", yes_no(status.pv_eoi));
+            }
+            Hypervisor::Other(_) => {}
+        }
+        if let Some(ns) = paravirt::clock_ns() {
+            println!("  Clock:            {} ns", ns);
+        }
+        if let Some(hz) = paravirt::tsc_frequency() {
+            println!("  TSC frequency:    {} Hz", hz);
+        }
+
+        if let Some(version) = vmbus::version() {
+            let channels = vmbus::channels();
+            println!("VMBus {}.{}: {} channels", version >> 16, version & 0xffff, channels.len());
+            println!("  RELID  CLASS               INTERFACE / INSTANCE");
+            for channel in channels {
+                println!(
+                    "{:>7}  {:<18}  {}",
+                    channel.relid,
+                    channel.class_name().unwrap_or("unknown"),
+                    channel.interface
+                );
+                println!("{:>29}{}", "", channel.instance);
+            }
+        }
+    }
+
+    fn cmd_namespaces(&self) {
+        let namespaces = crate::container::nsproxy::list();
+        if namespaces.is_empty() {
+            println!("All processes are in the initial namespaces");
+            return;
+        }
+        println!("KIND    ID  PROCS  DETAIL");
+        for ns in namespaces {
+            println!("{:<4} {:>5} {:>6}  {}", ns.kind, ns.id, ns.processes, ns.detail);
+        }
+    }
+
+    fn cmd_groups(&self) {
+        let groups = crate::container::group::list();
+        if groups.is_empty() {
+            println!("No resource groups");
+            return;
+        }
+        let limit = |value: Option<u64>| value.map_or(String::from("-"), |value| format!("{}", value));
+        println!("  ID  PARENT  NAME              SHARES  MEMORY (USED/LIMIT)     IO BYTES (LIMIT/s)      TICKS  PROCS");
+        for group in groups {
+            println!(
+                "{:>4} {:>7}  {:<16} {:>7}  {:>10}/{:<12} {:>10}/{:<12} {:>6} {:>6}",
+                group.id,
+                group.parent,
+                group.name,
+                group.cpu_shares,
+                group.memory_usage,
+                limit(group.memory_limit),
+                group.io_bytes,
+                limit(group.io_bandwidth),
+                group.cpu_ticks,
+                group.processes
+            );
+        }
+    }
+
+    fn cmd_checkpoint(&self, args: &[&str]) {
+        use crate::process::checkpoint;
+
+        if !accounts::caller_is_admin() {
+            access_denied("checkpoint");
+            return;
+        }
+
+        let (pid, path, stop) = match args {
+            [pid, path] => (pid.parse::<u32>(), path, false),
+            [pid, path, "stop"] => (pid.parse::<u32>(), path, true),
+            _ => {
+                usage("checkpoint pid file [stop]");
+                return;
+            }
+        };
+        let Ok(pid) = pid else {
+            fail!("checkpoint: invalid pid");
+            return;
+        };
+        match checkpoint::checkpoint(pid, path, stop) {
+            Ok(summary) => println!(
+                "Saved {} ({}) to {}: {} pages, {} descriptors, {} bytes{}",
+                pid,
+                summary.name,
+                path,
+                summary.pages,
+                summary.descriptors,
+                summary.size,
+                if stop { "; process stopped" } else { "" }
+            ),
+            Err(error) => fail!("checkpoint: {}", error),
+        }
+    }
+
+    fn cmd_restore(&self, args: &[&str]) {
+        if !accounts::caller_is_admin() {
+            access_denied("restore");
+            return;
+        }
+
+        match args {
+            [path] => match crate::process::checkpoint::restore(path) {
+                Ok(pid) => println!("Restored as process {}", pid),
+                Err(error) => fail!("restore: {}", error),
+            },
+            _ => usage("restore file"),
+        }
+    }
+
+    fn cmd_clocksource(&self, args: &[&str]) {
+        use crate::time::{clocksource, hpet, hrtimer, tsc};
+        use crate::time::clocksource::ClockSource;
+
+        match args {
+            [] => {}
+            [name] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("clocksource");
+                    return;
+                }
+                let Some(source) = ClockSource::from_name(name) else {
+                    fail!("clocksource: unknown source '{}'", name);
+                    return;
+                };
+                match clocksource::select(source) {
+                    Ok(()) => println!("Switched to {}", source.name()),
+                    Err(error) => fail!("clocksource: {}", error),
+                }
+                return;
+            }
+            _ => {
+                usage("clocksource [name]");
+                return;
+            }
+        }
+
+        let now = clocksource::now_ns();
+        let current = clocksource::current();
+        println!("Clocksource: {} ({} Hz), now {} ns", current.name(), current.frequency(), now);
+        for source in clocksource::available() {
+            println!("  {:<10} rating {:>3}{}", source.name(), source.rating(), if source == current { "  *" } else { "" });
+        }
+        println!(
+            "TSC: {} Hz from {}, {}",
+            tsc::frequency(),
+            tsc::source().map_or("unknown", |source| source.name()),
+            if tsc::invariant() { "invariant" } else { "not invariant" }
+        );
+        if hpet::present() {
+            println!(
+                "HPET: {} Hz, {} comparators, {}",
+                hpet::frequency(),
+                hpet::comparators(),
+                if hpet::has_event_timer() { "MSI event comparator" } else { "no event comparator" }
+            );
+        } else {
+            println!("HPET: not present");
+        }
+
+        let pending = hrtimer::pending();
+        println!("hrtimers: {} pending, {} fired", pending.len(), hrtimer::expired_count());
+        for (id, expires) in pending {
+            println!("  {:>6}  in {} ns", id, expires.saturating_sub(now));
+        }
+    }
+
+    fn cmd_idle(&self, args: &[&str]) {
+        use crate::power::idle;
+
+        match args {
+            [] => {}
+            [limit] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("idle");
+                    return;
+                }
+                let limit = if *limit == "none" { Some(u32::MAX) } else { limit.parse().ok() };
+                match limit {
+                    Some(limit) => idle::set_latency_limit(limit),
+                    None => fail!("idle: latency must be microseconds or 'none'"),
+                }
+                return;
+            }
+            _ => {
+                usage("idle [latency_us|none]");
+                return;
+            }
+        }
+
+        let states = idle::states();
+        match idle::latency_limit() {
+            u32::MAX => println!("Idle states (no latency limit):"),
+            limit => println!("Idle states (exit latency limit {} us):", limit),
+        }
+        for state in &states {
+            println!(
+                "  {:<5} {:?}, exit {} us, target {} us",
+                state.name, state.method, state.exit_latency_us, state.target_residency_us
+            );
+        }
+        println!("HPET wakeups: {}", if idle::hpet_wakeups() { "yes" } else { "no" });
+
+        for cpu in (0..crate::smp::MAX_CPUS).filter(|&cpu| cpu == 0 || crate::smp::cpu_online(cpu as u32)) {
+            let stats = idle::stats(cpu);
+            println!("CPU {}: idle {} ms, {} tickless", cpu, stats.idle_ns / 1_000_000, stats.tickless);
+            for (state, (usage, time_ns)) in states.iter().zip(stats.usage.iter().zip(&stats.time_ns)) {
+                println!("  {:<5} {:>10} entries {:>10} ms", state.name, usage, time_ns / 1_000_000);
+            }
+        }
+    }
+
+    fn cmd_cpu(&self, args: &[&str]) {
+        use crate::smp::{cpustat, hotplug, CpuState, SMP_MANAGER};
+
+        match args {
+            [] => {}
+            [action @ ("online" | "offline"), cpu] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("cpu");
+                    return;
+                }
+                let Ok(cpu) = cpu.parse::<u32>() else {
+                    fail!("cpu: CPU must be a number");
+                    return;
+                };
+                let result = if *action == "online" {
+                    hotplug::cpu_up(cpu).map(|()| String::from("online"))
+                } else {
+                    hotplug::cpu_down(cpu).map(|moved| format!("offline, {} threads moved", moved))
+                };
+                match result {
+                    Ok(state) => println!("CPU {} {}", cpu, state),
+                    Err(error) => fail!("cpu: {:?}", error),
+                }
+                return;
+            }
+            _ => {
+                usage("cpu [online|offline n]");
+                return;
+            }
+        }
+
+        let states: Vec<(u32, CpuState, u8)> = {
+            let smp = SMP_MANAGER.lock();
+            (0..crate::smp::MAX_CPUS as u32)
+                .filter_map(|cpu| smp.get_cpu(cpu).map(|info| (cpu, info.state, info.apic_id)))
+                .collect()
+        };
+        println!("{:>4} {:<8} {:>5} {:>6} {:>6} {:>6} {:>6}", "CPU", "STATE", "APIC", "USER%", "KERN%", "IRQ%", "IDLE%");
+        for (cpu, state, apic_id) in states {
+            let times = cpustat::times(cpu);
+            println!(
+                "{:>4} {:<8} {:>5} {:>6} {:>6} {:>6} {:>6}",
+                cpu,
+                format!("{:?}", state),
+                apic_id,
+                times.percent(times.user_ns),
+                times.percent(times.kernel_ns),
+                times.percent(times.irq_ns),
+                times.percent(times.idle_ns)
+            );
+        }
+        let routes = crate::acpi::apic::irq_routes();
+        if !routes.is_empty() {
+            println!("Interrupt routes:");
+            for route in routes {
+                println!("  GSI {:>3} vector {:#04x} -> APIC {}", route.gsi, route.vector, route.dest);
+            }
+        }
+    }
+
+    fn cmd_thermal(&self, args: &[&str]) {
+        use crate::thermal::{self, ThermalPolicy};
+
+        match args {
+            [] | ["status"] => {}
+            ["policy", name] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("thermal");
+                    return;
+                }
+                let Some(policy) = ThermalPolicy::from_name(name) else {
+                    return usage("thermal policy quiet|balanced|performance");
+                };
+                match thermal::set_thermal_policy(policy) {
+                    Ok(()) => println!("Thermal policy: {}", policy.name()),
+                    Err(error) => fail!("thermal: {}", error),
+                }
+                return;
+            }
+            _ => return usage("thermal [status | policy quiet|balanced|performance]"),
+        }
+
+        let status = thermal::status();
+        println!(
+            "Thermal policy: {}{}",
+            status.policy.name(),
+            if status.throttling_active { ", throttling" } else { "" }
+        );
+        println!("{:<4} {:<10} {:>8}  {}", "Zone", "Name", "Temp", "Trip points");
+        for zone in &status.zones {
+            let trips: Vec<String> = zone
+                .trip_points
+                .iter()
+                .map(|trip| format!("{} {}", format!("{:?}", trip.trip_type).to_lowercase(), celsius(trip.temperature)))
+                .collect();
+            println!("{:<4} {:<10} {:>8}  {}", zone.id, zone.name, celsius(zone.current_temp), trips.join(", "));
+        }
+        Self::print_cooling_devices(&status.cooling_devices);
+    }
+
+    fn print_cooling_devices(devices: &[crate::thermal::CoolingDevice]) {
+        println!("{:<4} {:<12} {:<10} {:>9} {:>5}", "ID", "Cooling", "Type", "State", "%");
+        for device in devices {
+            let range = device.max_state - device.min_state;
+            let percent = match range {
+                0 => 0,
+                range => (device.current_state - device.min_state) * 100 / range,
+            };
+            println!(
+                "{:<4} {:<12} {:<10} {:>9} {:>4}%",
+                device.id,
+                device.name,
+                format!("{:?}", device.device_type),
+                format!("{}/{}", device.current_state, device.max_state),
+                percent
+            );
+        }
+    }
+
+    fn cmd_fan(&self, args: &[&str]) {
+        use crate::thermal::{self, CoolingDeviceType};
+
+        match args {
+            [] => {
+                let mut fans = thermal::status().cooling_devices;
+                fans.retain(|device| device.device_type == CoolingDeviceType::Fan);
+                Self::print_cooling_devices(&fans);
+            }
+            ["set", id, percent] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("fan");
+                    return;
+                }
+                let (Ok(id), Ok(percent)) = (id.parse::<u32>(), percent.trim_end_matches('%').parse::<u32>()) else {
+                    return usage("fan set id percent");
+                };
+                match thermal::set_fan_percent(id, percent) {
+                    Ok(state) => println!("Fan {} set to {}% (state {})", id, percent, state),
+                    Err(error) => fail!("fan: {}", error),
+                }
+            }
+            _ => usage("fan [set id percent]"),
+        }
+    }
+
+    fn cmd_cpufreq(&self, args: &[&str]) {
+        use crate::power::{self, cpufreq, governor::CpuGovernor};
+
+        match args {
+            [] => {
+                let status = cpufreq::status();
+                println!("Governor:  {}", status.governor.name());
+                match status.current_mhz {
+                    0 => println!("Current:   unknown"),
+                    mhz => println!("Current:   {} MHz", mhz),
+                }
+                println!("Limits:    {} - {} MHz, base {} MHz", status.min_mhz, status.max_mhz, status.base_mhz);
+                println!("Turbo:     {}", if status.turbo_enabled { "on" } else { "off" });
+                println!("P-states:  {}", status.p_states);
+            }
+            ["governor"] => {
+                let current = cpufreq::governor();
+                for governor in CpuGovernor::ALL {
+                    println!("{} {}", if governor == current { "*" } else { " " }, governor.name());
+                }
+            }
+            ["governor", name] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("cpufreq");
+                    return;
+                }
+                let Some(governor) = CpuGovernor::from_name(name) else {
+                    fail!("cpufreq: no governor '{}'; 'cpufreq governor' lists them", name);
+                    return;
+                };
+                match power::set_cpu_governor(governor) {
+                    Ok(()) => println!("Governor: {}", governor.name()),
+                    Err(error) => fail!("cpufreq: {}", error),
+                }
+            }
+            _ => usage("cpufreq [governor [name]]"),
+        }
+    }
+
+    fn cmd_powercfg(&self, args: &[&str]) {
+        use crate::power;
+
+        match args {
+            [option] if option.eq_ignore_ascii_case("/a") => {
+                println!("The following sleep states are available on this system:");
+                if power::suspend_enabled() {
+                    println!("    Standby (S3)");
+                }
+                if power::hibernate_enabled() {
+                    println!("    Hibernate");
+                }
+                println!("The following sleep states are not available on this system:");
+                if !power::suspend_enabled() {
+                    println!("    Standby (S3)");
+                }
+                if !power::hibernate_enabled() {
+                    match power::hibernate::available() {
+                        Ok(()) => println!("    Hibernate: not enabled; run powercfg /hibernate on"),
+                        Err(reason) => println!("    Hibernate: {}", reason),
+                    }
+                }
+            }
+            [option, state]
+                if option.eq_ignore_ascii_case("/hibernate") || option.eq_ignore_ascii_case("/h") =>
+            {
+                let enable = match state.to_ascii_lowercase().as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return usage("powercfg /hibernate on|off"),
+                };
+                if !accounts::caller_is_admin() {
+                    access_denied("powercfg");
+                    return;
+                }
+                if let Err(error) = power::set_hibernate_enabled(enable) {
+                    fail!("powercfg: {}", error);
+                }
+            }
+            [option, query] if option.eq_ignore_ascii_case("/devicequery") => {
+                let armed_only = match query.to_ascii_lowercase().as_str() {
+                    "wake_armed" => true,
+                    "wake_programmable" => false,
+                    _ => return usage("powercfg /devicequery wake_armed|wake_programmable"),
+                };
+                let sources = power::device::wake_sources();
+                let devices = crate::driver::DEVICES.lock();
+                let mut listed = 0;
+                for source in sources.iter().filter(|source| source.enabled || !armed_only) {
+                    println!("{} ({})", devices.path(source.device), source.kind.name());
+                    listed += 1;
+                }
+                if listed == 0 {
+                    println!("NONE");
+                }
+            }
+            [option, path]
+                if option.eq_ignore_ascii_case("/deviceenablewake")
+                    || option.eq_ignore_ascii_case("/devicedisablewake") =>
+            {
+                if !accounts::caller_is_admin() {
+                    access_denied("powercfg");
+                    return;
+                }
+                let Some(id) = crate::driver::DEVICES.lock().lookup(path).map(|device| device.id) else {
+                    fail!("powercfg: no device {}", path);
+                    return;
+                };
+                let enable = option.eq_ignore_ascii_case("/deviceenablewake");
+                if let Err(error) = power::device::enable_wake(id, enable) {
+                    fail!("powercfg: {}", error);
+                }
+            }
+            [option] if option.eq_ignore_ascii_case("/lastwake") => {
+                let sources = power::device::wake_sources();
+                match power::device::last_wake() {
+                    Some(id) => {
+                        let kind = sources.iter().find(|source| source.device == id).map(|source| source.kind.name());
+                        let path = crate::driver::DEVICES.lock().path(id);
+                        println!("Wake source: {} ({})", path, kind.unwrap_or("device"));
+                    }
+                    None => println!("Wake source: unknown"),
+                }
+                for source in sources.iter().filter(|source| source.count > 0) {
+                    let path = crate::driver::DEVICES.lock().path(source.device);
+                    println!("    {}: {} wakes", path, source.count);
+                }
+            }
+            _ => usage("powercfg /a | /hibernate on|off | /devicequery wake_armed|wake_programmable | /lastwake"),
+        }
+    }
+
+    fn cmd_input(&self, args: &[&str]) {
+        use crate::{i2c, input};
+
+        const USAGE: &str =
+            "input [events [count] | keymap [name | load file] | ime [name | off | load file] | repeat [delay period]]";
+
+        match args {
+            [] => {
+                let mut devices = input::devices();
+                devices.retain(|device| device.present);
+                if devices.is_empty() {
+                    println!("No input devices");
+                }
+                for device in devices {
+                    let kind = device.kind.name();
+                    let grab = match input::evdev::grabbed_by(device.index) {
+                        Some(pid) => format!("  (grabbed by {})", pid),
+                        None => String::new(),
+                    };
+                    let (index, events) = (device.index, device.events);
+                    println!("input{:<3} {:<10} {:>8} events  {}{}", index, kind, events, device.name, grab);
+                }
+                for bus in i2c::buses() {
+                    println!("i2c-{:<4} {:<10} {} kHz", bus.index, bus.driver, bus.speed_hz / 1000);
+                }
+            }
+            ["events", rest @ ..] => {
+                let count = match rest {
+                    [] => 64,
+                    [count] => match count.parse::<usize>() {
+                        Ok(count) => count,
+                        Err(_) => return usage("input events [count]"),
+                    },
+                    _ => return usage("input events [count]"),
+                };
+                for event in input::read(count) {
+                    let key = if event.kind == input::EV_KEY { input::key_name(event.code) } else { None };
+                    println!(
+                        "{:>6}.{:06} input{} type {} code {} value {}{}",
+                        event.time_ns / 1_000_000_000,
+                        event.time_ns % 1_000_000_000 / 1000,
+                        event.device,
+                        event.kind,
+                        event.code,
+                        event.value,
+                        key.map(|name| format!(" ({})", name)).unwrap_or_default()
+                    );
+                }
+            }
+            ["keymap"] => {
+                let current = input::keymap::current();
+                for layout in input::keymap::layouts() {
+                    let mark = if core::ptr::eq(layout, current) { '*' } else { ' ' };
+                    println!("{} {:<8} {}", mark, layout.name, layout.description);
+                }
+            }
+            ["keymap", "load", path] => match input::keymap::load(path) {
+                Ok(layout) => println!("Loaded keymap {}", layout.name),
+                Err(error) => fail!("input: {}", error),
+            },
+            ["keymap", name] => match input::keymap::find(name) {
+                Some(layout) => input::keymap::set(layout),
+                None => fail!("input: no keymap '{}'", name),
+            },
+            ["ime"] => {
+                let (methods, selected, enabled) = input::ime::methods();
+                for (index, (name, description)) in methods.iter().enumerate() {
+                    let mark = if index == selected && enabled { '*' } else { ' ' };
+                    println!("{} {:<8} {}", mark, name, description);
+                }
+                if !enabled {
+                    println!("Input method off; Ctrl+Space turns it on");
+                }
+            }
+            ["ime", "off"] => input::ime::set_enabled(false),
+            ["ime", "load", path] => match input::ime::table::load(path) {
+                Ok(name) => println!("Loaded input method {}", name),
+                Err(error) => fail!("input: {}", error),
+            },
+            ["ime", name] => {
+                if input::ime::select(name).is_err() {
+                    fail!("input: no input method '{}'", name);
+                }
+            }
+            ["repeat"] => {
+                let (delay, period) = input::repeat_rate();
+                println!("Key repeat: after {} ms, every {} ms", delay, period);
+            }
+            ["repeat", delay, period] => match (delay.parse(), period.parse()) {
+                (Ok(delay), Ok(period)) => input::set_repeat_rate(delay, period),
+                _ => usage("input repeat delay_ms period_ms"),
+            },
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_hotkey(&self, args: &[&str]) {
+        use crate::acpi::ec;
+        use crate::input;
+        use crate::power::rfkill;
+
+        let parse_query = |text: &str| u8::from_str_radix(text.trim_start_matches("0x"), 16).ok();
+        match args {
+            [] => {
+                match ec::info() {
+                    Some(info) => {
+                        println!("EC:          ports 0x{:x}/0x{:x}", info.command_port, info.data_port);
+                        for mapping in &info.mappings {
+                            let key = input::key_name(mapping.key).unwrap_or("?");
+                            let source = if mapping.from_tables { "from the tables" } else { "mapped" };
+                            println!("  query 0x{:02X}  {:<15} {}", mapping.query, key, source);
+                        }
+                        if !info.unmapped.is_empty() {
+                            let queries: Vec<String> = info.unmapped.iter().map(|q| format!("0x{:02X}", q)).collect();
+                            println!("  unmapped:   {}", queries.join(" "));
+                        }
+                    }
+                    None => println!("EC:          none"),
+                }
+                match crate::gpu::backlight::percent() {
+                    Ok(percent) => println!("Brightness:  {}%", percent),
+                    Err(error) => println!("Brightness:  {}", error),
+                }
+                let volume = crate::sound::AUDIO_MANAGER.lock().get_volume();
+                let muted = if input::hotkey::muted() { ", muted" } else { "" };
+                println!("Volume:      {}%{}", (volume * 100.0) as u32, muted);
+                for radio in rfkill::RADIOS {
+                    let state = if rfkill::blocked(radio) { "blocked" } else { "on" };
+                    println!("{:<12} {}", format!("{}:", radio.name()), state);
+                }
+            }
+            ["map", query, key] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("hotkey");
+                    return;
+                }
+                let (Some(query), Some(key)) = (parse_query(query), input::key_code(key)) else {
+                    let names: Vec<&str> = input::KEY_NAMES.iter().map(|&(_, name)| name).collect();
+                    fail!("hotkey: give the query in hex and one of: {}", names.join(", "));
+                    return;
+                };
+                if let Err(error) = ec::map(query, Some(key)) {
+                    fail!("hotkey: {}", error);
+                }
+            }
+            ["unmap", query] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("hotkey");
+                    return;
+                }
+                let Some(query) = parse_query(query) else {
+                    return usage("hotkey unmap query");
+                };
+                if let Err(error) = ec::map(query, None) {
+                    fail!("hotkey: {}", error);
+                }
+            }
+            _ => usage("hotkey [map query key | unmap query]"),
+        }
+    }
+
+    fn cmd_gamepad(&self, args: &[&str]) {
+        use crate::input::gamepad;
+        use crate::win32::xinput::{self, XInputCapabilities, XInputState};
+
+        const USAGE: &str = "gamepad [test player | rumble player low% high% [ms]]";
+        // How long `test` rumbles for
+        const TEST_RUMBLE_MS: u64 = 300;
+        const BUTTON_NAMES: [(u16, &str); 14] = [
+            (gamepad::A, "A"),
+            (gamepad::B, "B"),
+            (gamepad::X, "X"),
+            (gamepad::Y, "Y"),
+            (gamepad::LEFT_SHOULDER, "LB"),
+            (gamepad::RIGHT_SHOULDER, "RB"),
+            (gamepad::BACK, "Back"),
+            (gamepad::START, "Start"),
+            (gamepad::LEFT_THUMB, "LS"),
+            (gamepad::RIGHT_THUMB, "RS"),
+            (gamepad::DPAD_UP, "Up"),
+            (gamepad::DPAD_DOWN, "Down"),
+            (gamepad::DPAD_LEFT, "Left"),
+            (gamepad::DPAD_RIGHT, "Right"),
+        ];
+
+        // Players are numbered from 1, as the pads' lights show them
+        let player = |text: &str| {
+            let player = text.parse::<usize>().ok()?;
+            (1..=gamepad::MAX_PADS).contains(&player).then(|| player - 1)
+        };
+        let speed = |text: &str| {
+            let percent = text.trim_end_matches('%').parse::<u32>().ok()?;
+            (percent <= 100).then(|| (percent * u16::MAX as u32 / 100) as u16)
+        };
+        match args {
+            [] => {
+                let pads = gamepad::pads();
+                if pads.is_empty() {
+                    println!("No gamepads");
+                }
+                for pad in pads {
+                    let rumble = if pad.can_rumble { "rumble" } else { "no rumble" };
+                    let (player, input, packet) = (pad.slot + 1, pad.input, pad.packet);
+                    println!("player {}  input{:<3} {:>8} packets  {:<9}  {}", player, input, packet, rumble, pad.name);
+                }
+            }
+            ["test", slot] => {
+                let Some(slot) = player(slot) else {
+                    return usage(USAGE);
+                };
+                let mut capabilities = XInputCapabilities::default();
+                let mut state = XInputState::default();
+                let result = xinput::XInputGetCapabilities(slot as u32, 0, &mut capabilities);
+                let result = if result == 0 { xinput::XInputGetState(slot as u32, &mut state) } else { result };
+                if result == xinput::ERROR_DEVICE_NOT_CONNECTED {
+                    fail!("gamepad: no pad is player {}", slot + 1);
+                    return;
+                } else if result != 0 {
+                    fail!("gamepad: XInput error {}", result);
+                    return;
+                }
+                let pad = state.gamepad;
+                let pressed: Vec<&str> =
+                    BUTTON_NAMES.iter().filter(|&&(bit, _)| pad.buttons & bit != 0).map(|&(_, name)| name).collect();
+                println!("Packet:    {}", state.packet_number);
+                println!("Buttons:   {}", if pressed.is_empty() { String::from("none") } else { pressed.join(" ") });
+                println!("Triggers:  {} {}", pad.left_trigger, pad.right_trigger);
+                println!("Left:      {} {}", pad.thumb_lx, pad.thumb_ly);
+                println!("Right:     {} {}", pad.thumb_rx, pad.thumb_ry);
+                if capabilities.vibration.left_motor_speed == 0 {
+                    println!("Rumble:    none");
+                } else if let Err(error) = gamepad::rumble_for(slot, u16::MAX / 2, u16::MAX / 2, TEST_RUMBLE_MS) {
+                    fail!("gamepad: {}", error);
+                } else {
+                    println!("Rumble:    {} ms", TEST_RUMBLE_MS);
+                }
+            }
+            ["rumble", slot, low, high, rest @ ..] => {
+                let ms = match rest {
+                    [] => None,
+                    [ms] => match ms.parse::<u64>() {
+                        Ok(ms) => Some(ms),
+                        Err(_) => return usage(USAGE),
+                    },
+                    _ => return usage(USAGE),
+                };
+                let (Some(slot), Some(low), Some(high)) = (player(slot), speed(low), speed(high)) else {
+                    return usage(USAGE);
+                };
+                let result = match ms {
+                    Some(ms) => gamepad::rumble_for(slot, low, high, ms),
+                    None => gamepad::set_vibration(slot, low, high),
+                };
+                if let Err(error) = result {
+                    fail!("gamepad: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_camera(&self, args: &[&str]) {
+        use crate::multimedia::capture::CAPTURE_MANAGER;
+        use crate::multimedia::{MediaFormat, PixelFormat};
+        use crate::usb::uvc;
+
+        const USAGE: &str = "camera [formats n | set n WxH [yuyv|nv12|mjpeg] | grab n file]";
+        // How long `grab` waits for a frame
+        const GRAB_TIMEOUT_MS: u64 = 3000;
+
+        let describe = |format: &MediaFormat| {
+            let (width, height) = (format.width.unwrap_or(0), format.height.unwrap_or(0));
+            let pixels = match format.pixel_format {
+                Some(PixelFormat::YUYV) => "YUYV",
+                Some(PixelFormat::NV12) => "NV12",
+                Some(PixelFormat::MJPEG) => "MJPEG",
+                _ => "?",
+            };
+            format!("{}x{} {} {:.1} fps", width, height, pixels, format.framerate.unwrap_or(0.0))
+        };
+        // Cameras are numbered from 0, as video capture drivers are
+        let cameras = uvc::cameras();
+        let camera = |text: &str| {
+            let camera = cameras.get(text.parse::<usize>().ok()?)?;
+            CAPTURE_MANAGER.get_device(&camera.name)
+        };
+        match args {
+            [] => {
+                if cameras.is_empty() {
+                    println!("No cameras");
+                }
+                for (number, camera) in cameras.iter().enumerate() {
+                    let state = if camera.streaming { "streaming" } else { "idle" };
+                    println!(
+                        "{}  UVC {:x}.{:02x}  {:<24} {:<9} {:>6} frames {:>5} dropped  {}",
+                        number,
+                        camera.version >> 8,
+                        camera.version & 0xFF,
+                        describe(&camera.format),
+                        state,
+                        camera.captured,
+                        camera.dropped,
+                        camera.name
+                    );
+                }
+            }
+            ["formats", number] => {
+                let Some(device) = camera(number) else {
+                    return usage(USAGE);
+                };
+                for format in device.read().get_capabilities().supported_formats {
+                    println!("  {}", describe(&format));
+                }
+            }
+            ["set", number, size, rest @ ..] => {
+                let pixel_format = match rest {
+                    [] => None,
+                    ["yuyv"] => Some(PixelFormat::YUYV),
+                    ["nv12"] => Some(PixelFormat::NV12),
+                    ["mjpeg"] => Some(PixelFormat::MJPEG),
+                    _ => return usage(USAGE),
+                };
+                let size = size.split_once('x');
+                let size = size.and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)));
+                let (Some(device), Some((width, height))) = (camera(number), size) else {
+                    return usage(USAGE);
+                };
+                let mut format = device.read().get_format();
+                format.width = Some(width);
+                format.height = Some(height);
+                format.framerate = None;
+                format.pixel_format = pixel_format.or(format.pixel_format);
+                let result = device.write().set_format(&format);
+                match result {
+                    Ok(()) => println!("Camera {} now {}", number, describe(&device.read().get_format())),
+                    Err(error) => fail!("camera: cannot set {}x{}: {:?}", width, height, error),
+                }
+            }
+            ["grab", number, path] => {
+                let Some(device) = camera(number) else {
+                    return usage(USAGE);
+                };
+                if let Err(error) = device.write().open() {
+                    return fail!("camera: cannot open camera {}: {:?}", number, error);
+                }
+                let frame = device.write().start_capture().and_then(|()| {
+                    let frame = uvc::next_frame(&device, GRAB_TIMEOUT_MS);
+                    let _ = device.write().stop_capture();
+                    frame
+                });
+                let _ = device.write().close();
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(error) => return fail!("camera: no frame from camera {}: {:?}", number, error),
+                };
+                // JPEG frames are saved as they came, the others as a PPM
+                let data = match frame.pixel_format {
+                    PixelFormat::MJPEG => frame.data,
+                    _ => match uvc::rgb24(&frame) {
+                        Some(rgb) => {
+                            let mut data = format!("P6\n{} {}\n255\n", frame.width, frame.height).into_bytes();
+                            data.extend_from_slice(&rgb);
+                            data
+                        }
+                        None => return fail!("camera: cannot convert the frame"),
+                    },
+                };
+                match crate::fs::vfs::VFS.lock().write_file(path, &data) {
+                    Ok(()) => {
+                        println!("Saved a {}x{} frame, {} bytes, to {}", frame.width, frame.height, data.len(), path)
+                    }
+                    Err(_) => fail!("camera: cannot write '{}'", path),
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_bluetooth(&self, args: &[&str]) {
+        use crate::bluetooth::{self, AdapterState, BluetoothAddress, BluetoothDevice, LinkStatus};
+        use crate::drivers::bluetooth::uart;
+
+        const USAGE: &str =
+            "bluetooth [scan [s] | devices | connect addr [pin] | disconnect|unpair addr | attach n [baud] | detach n]";
+        const DEFAULT_SCAN_SECONDS: u32 = 10;
+        // Name requests follow an inquiry, and each may take a few seconds
+        const NAMING_MS: u64 = 20_000;
+        // Long enough to pair, with the user typing a PIN on a keyboard
+        const CONNECT_TIMEOUT_MS: u64 = 60_000;
+
+        let show = |device: &BluetoothDevice| {
+            let status = match device.status {
+                LinkStatus::Disconnected => String::from("-"),
+                LinkStatus::Connecting => String::from("connecting"),
+                LinkStatus::Securing => String::from("pairing"),
+                LinkStatus::Connected => String::from("connected"),
+                LinkStatus::Failed(error) => format!("failed: {:?}", error),
+            };
+            let rssi = device.rssi.map_or(String::new(), |rssi| format!("{} dBm", rssi));
+            println!(
+                "{}  {:06x}  {:<6} {:>8}  {:<12} {}",
+                device.address,
+                device.class,
+                if device.paired { "paired" } else { "" },
+                rssi,
+                status,
+                device.name.as_deref().unwrap_or("?")
+            );
+        };
+        let list_devices = || match bluetooth::devices(None) {
+            Ok(devices) if devices.is_empty() => println!("No devices"),
+            Ok(devices) => devices.iter().for_each(show),
+            Err(error) => fail!("bluetooth: {:?}", error),
+        };
+        let address = |text: &str| text.parse::<BluetoothAddress>().ok();
+        match args {
+            [] => {
+                let adapters = bluetooth::adapters();
+                if adapters.is_empty() {
+                    println!("No Bluetooth adapters");
+                }
+                for adapter in adapters {
+                    let state = match adapter.state {
+                        AdapterState::Starting => "starting",
+                        AdapterState::Ready if adapter.scanning => "scanning",
+                        AdapterState::Ready => "ready",
+                        AdapterState::Failed => "failed",
+                    };
+                    println!(
+                        "{}  {}  HCI {}  {:<8} {} links  {}",
+                        adapter.name, adapter.address, adapter.hci_version, state, adapter.links, adapter.description
+                    );
+                }
+            }
+            ["scan", rest @ ..] => {
+                let seconds = match rest {
+                    [] => DEFAULT_SCAN_SECONDS,
+                    [seconds] => match seconds.parse::<u32>() {
+                        Ok(seconds) if (1..=60).contains(&seconds) => seconds,
+                        _ => return usage(USAGE),
+                    },
+                    _ => return usage(USAGE),
+                };
+                if let Err(error) = bluetooth::start_inquiry(None, seconds) {
+                    return fail!("bluetooth: cannot scan: {:?}", error);
+                }
+                println!("Scanning for {} seconds...", seconds);
+                if !bluetooth::wait(seconds as u64 * 1000 + NAMING_MS, || !bluetooth::scanning(None)) {
+                    println!("Some names are still to come");
+                }
+                list_devices();
+            }
+            ["devices"] => list_devices(),
+            ["connect", text, rest @ ..] => {
+                let pin = match rest {
+                    [] => None,
+                    [pin] => Some(*pin),
+                    _ => return usage(USAGE),
+                };
+                let Some(address) = address(text) else {
+                    return usage(USAGE);
+                };
+                let index = match bluetooth::connect(None, address, pin) {
+                    Ok(index) => index,
+                    Err(error) => return fail!("bluetooth: cannot connect to {}: {:?}", address, error),
+                };
+                let status = || bluetooth::device(Some(index), address).map(|device| device.status);
+                bluetooth::wait(CONNECT_TIMEOUT_MS, || {
+                    matches!(status(), Some(LinkStatus::Connected | LinkStatus::Failed(_)) | None)
+                });
+                match status() {
+                    Some(LinkStatus::Connected) => println!("Connected to {}", address),
+                    Some(LinkStatus::Failed(error)) => fail!("bluetooth: cannot connect to {}: {:?}", address, error),
+                    _ => fail!("bluetooth: {} did not answer", address),
+                }
+            }
+            ["disconnect", text] => {
+                let Some(address) = address(text) else {
+                    return usage(USAGE);
+                };
+                if let Err(error) = bluetooth::disconnect(None, address) {
+                    fail!("bluetooth: cannot disconnect {}: {:?}", address, error);
+                }
+            }
+            ["unpair", text] => {
+                let Some(address) = address(text) else {
+                    return usage(USAGE);
+                };
+                if let Err(error) = bluetooth::unpair(None, address) {
+                    fail!("bluetooth: cannot unpair {}: {:?}", address, error);
+                }
+            }
+            ["attach", port, rest @ ..] => {
+                let baud = match rest {
+                    [] => crate::serial::DEFAULT_BAUD,
+                    [baud] => match baud.parse::<u32>() {
+                        Ok(baud) => baud,
+                        Err(_) => return usage(USAGE),
+                    },
+                    _ => return usage(USAGE),
+                };
+                let Ok(port) = port.parse::<usize>() else {
+                    return usage(USAGE);
+                };
+                match uart::attach(port, baud) {
+                    Ok(index) => println!("ttyS{} is hci{}", port, index),
+                    Err(error) => fail!("bluetooth: cannot attach ttyS{}: {:?}", port, error),
+                }
+            }
+            ["detach", port] => {
+                let Ok(port) = port.parse::<usize>() else {
+                    return usage(USAGE);
+                };
+                if let Err(error) = uart::detach(port) {
+                    fail!("bluetooth: cannot detach ttyS{}: {:?}", port, error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_wifi(&self, args: &[&str]) {
+        use crate::net::wireless::{self, Bss, Security, State};
+
+        const USAGE: &str = "wifi [scan | networks | connect ssid [passphrase] | disconnect]";
+        // Joining includes the handshake, and working out the PMK first
+        const SCAN_TIMEOUT_MS: u64 = 20_000;
+        const CONNECT_TIMEOUT_MS: u64 = 40_000;
+
+        let show = |bss: &Bss| {
+            let security = match bss.security() {
+                Security::Open => "open",
+                Security::Wpa2Psk => "WPA2",
+                Security::Unsupported => "other",
+            };
+            println!("{}  {:>3} dBm  ch {:<3} {:<6} {}", bss.bssid, bss.signal, bss.channel, security, bss.ssid_text());
+        };
+        let list_networks = |name: &str| match wireless::scan_results(name) {
+            Ok(results) if results.is_empty() => println!("No networks"),
+            Ok(results) => results.iter().for_each(show),
+            Err(error) => fail!("wifi: {}", error),
+        };
+        let scan = |name: &str| -> Result<(), &'static str> {
+            wireless::scan(name)?;
+            wireless::wait(SCAN_TIMEOUT_MS, || wireless::state(name) != Ok(State::Scanning));
+            Ok(())
+        };
+
+        let interfaces = wireless::interfaces();
+        if args.is_empty() {
+            if interfaces.is_empty() {
+                println!("No Wi-Fi adapters");
+            }
+            for interface in interfaces {
+                let network = match (&interface.network, interface.error) {
+                    (Some(bss), _) => bss.ssid_text(),
+                    (None, Some(error)) => format!("({})", error),
+                    (None, None) => String::from("-"),
+                };
+                println!(
+                    "{}  {}  {:<11} {:<24} {}",
+                    interface.name,
+                    interface.mac,
+                    interface.state.name(),
+                    network,
+                    interface.description
+                );
+            }
+            return;
+        }
+        // Commands act on the first adapter
+        let Some(name) = interfaces.first().map(|interface| interface.name.clone()) else {
+            return fail!("wifi: no Wi-Fi adapters");
+        };
+        match args {
+            ["scan"] => {
+                if let Err(error) = scan(&name) {
+                    return fail!("wifi: cannot scan: {}", error);
+                }
+                list_networks(&name);
+            }
+            ["networks"] => list_networks(&name),
+            ["connect", ssid, rest @ ..] => {
+                let passphrase = match rest {
+                    [] => None,
+                    [passphrase] => Some(*passphrase),
+                    _ => return usage(USAGE),
+                };
+                let results = wireless::scan_results(&name).unwrap_or_default();
+                if !results.iter().any(|bss| bss.ssid == ssid.as_bytes()) {
+                    if let Err(error) = scan(&name) {
+                        return fail!("wifi: cannot scan: {}", error);
+                    }
+                }
+                if let Err(error) = wireless::connect(&name, ssid, passphrase) {
+                    return fail!("wifi: cannot connect to {}: {}", ssid, error);
+                }
+                wireless::wait(CONNECT_TIMEOUT_MS, || {
+                    !matches!(wireless::state(&name), Ok(State::Associating | State::Handshake))
+                });
+                let info = wireless::interfaces().into_iter().find(|interface| interface.name == name);
+                match info.map(|info| (info.state, info.error)) {
+                    Some((State::Connected, _)) => println!("{} connected to {}", name, ssid),
+                    Some((_, Some(error))) => fail!("wifi: cannot connect to {}: {}", ssid, error),
+                    _ => fail!("wifi: {} did not answer", ssid),
+                }
+            }
+            ["disconnect"] => {
+                if let Err(error) = wireless::disconnect(&name) {
+                    fail!("wifi: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_print(&self, args: &[&str]) {
+        use crate::printing::{self, job::JobPriority, PrintOptions};
+
+        const USAGE: &str = "print [/d:printer] [/p:high|normal|low] [/c:copies] file";
+        let (mut printer, mut priority, mut copies, mut path) = (None, JobPriority::Normal, 1, None);
+        for &arg in args {
+            let (option, value) = match arg.split_once(':') {
+                Some((option, value)) if option.starts_with('/') => (option.to_ascii_lowercase(), value),
+                _ if path.is_none() => {
+                    path = Some(arg);
+                    continue;
+                }
+                _ => return usage(USAGE),
+            };
+            match option.as_str() {
+                "/d" => printer = Some(value),
+                "/p" => {
+                    priority = match value.to_ascii_lowercase().as_str() {
+                        "high" => JobPriority::High,
+                        "normal" => JobPriority::Normal,
+                        "low" => JobPriority::Low,
+                        _ => return usage(USAGE),
+                    }
+                }
+                "/c" => match value.parse::<u32>() {
+                    Ok(count @ 1..=999) => copies = count,
+                    _ => return usage(USAGE),
+                },
+                _ => return usage(USAGE),
+            }
+        }
+        let Some(path) = path else {
+            return usage(USAGE);
+        };
+        let data = match VFS.lock().read_file(path) {
+            Ok(data) => data,
+            Err(_) => return fail!("Error: Cannot read file '{}'", path),
+        };
+
+        let subsystem = printing::get_subsystem().read();
+        let Some(subsystem) = subsystem.as_ref() else {
+            return fail!("print: printing is not running");
+        };
+        let printer = match printer {
+            Some(name) => subsystem.find_printer(name),
+            None => subsystem.get_default_printer().and_then(|id| subsystem.get_printer(id)),
+        };
+        let Some(printer) = printer else {
+            return fail!("print: no such printer; 'printer' lists them");
+        };
+        let user = accounts::token_user_name(crate::process::current_token()).unwrap_or_default();
+        let title = path.rsplit('/').next().unwrap_or(path).to_string();
+        let options = PrintOptions { copies, ..PrintOptions::default() };
+        match subsystem.submit_job(printer.id, user, title, data, options, priority) {
+            Ok(job) => println!("Job {} queued on {}", job, printer.name),
+            Err(error) => fail!("print: {}", error),
+        }
+    }
+
+    fn cmd_printer(&self, args: &[&str]) {
+        use crate::printing::{self, Language};
+
+        const USAGE: &str =
+            "printer [jobs | cancel job | add name uri [pcl|escp|raw] | remove|pause|resume|default name]";
+        match args {
+            [] => {
+                let subsystem = printing::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("printer: printing is not running");
+                };
+                let default = subsystem.get_default_printer();
+                println!("  {:<20} {:<6} {:<10} {:>5}  {}", "Printer", "Lang", "Status", "Queue", "URI");
+                let jobs = subsystem.list_jobs();
+                for printer in subsystem.list_printers() {
+                    let waiting = jobs.iter().filter(|j| j.printer_id == printer.id && !j.is_completed()).count();
+                    let status = subsystem.get_printer_status(printer.id).unwrap_or(printer.status);
+                    println!(
+                        "{} {:<20} {:<6} {:<10} {:>5}  {}",
+                        if default == Some(printer.id) { "*" } else { " " },
+                        printer.name,
+                        printer.language.name(),
+                        format!("{:?}", status),
+                        waiting,
+                        printer.uri
+                    );
+                }
+            }
+            ["jobs"] => {
+                let subsystem = printing::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("printer: printing is not running");
+                };
+                println!("{:>5} {:<16} {:<12} {:<10} {:>7}  {}", "Job", "Printer", "User", "Status", "Pages", "Title");
+                for job in subsystem.list_jobs() {
+                    let printer = subsystem.get_printer(job.printer_id).map_or(String::from("?"), |p| p.name);
+                    let pages = match job.total_pages {
+                        0 => format!("{}", job.pages_printed),
+                        total => format!("{}/{}", job.pages_printed, total),
+                    };
+                    println!(
+                        "{:>5} {:<16} {:<12} {:<10} {:>7}  {}",
+                        job.id,
+                        printer,
+                        job.user,
+                        format!("{:?}", job.status),
+                        pages,
+                        job.title
+                    );
+                    if let Some(error) = &job.error_message {
+                        println!("{:>5} {}", "", error);
+                    }
+                }
+            }
+            ["cancel", job] => {
+                let Ok(job) = job.parse::<u32>() else {
+                    return usage("printer cancel job");
+                };
+                let subsystem = printing::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("printer: printing is not running");
+                };
+                let Some(found) = subsystem.get_job(job).filter(|j| !j.is_completed()) else {
+                    return fail!("printer: no job {} waiting or printing", job);
+                };
+                let user = accounts::token_user_name(crate::process::current_token());
+                if user.as_deref() != Some(found.user.as_str()) && !accounts::caller_is_admin() {
+                    return access_denied("printer");
+                }
+                match subsystem.cancel_job(job) {
+                    Ok(()) => println!("Job {} cancelled", job),
+                    Err(error) => fail!("printer: {}", error),
+                }
+            }
+            ["add", name, uri, language @ ..] if language.len() <= 1 => {
+                let language = match language.first() {
+                    None => Language::Pcl,
+                    Some(language) => match Language::parse(language) {
+                        Some(language) => language,
+                        None => return usage("printer add name uri [pcl|escp|raw]"),
+                    },
+                };
+                if !accounts::caller_is_admin() {
+                    return access_denied("printer");
+                }
+                let mut subsystem = printing::get_subsystem().write();
+                let Some(subsystem) = subsystem.as_mut() else {
+                    return fail!("printer: printing is not running");
+                };
+                match subsystem.add_network_printer(name, uri, language) {
+                    Ok(_) => {
+                        crate::drivers::printing::print_add_printer(name, uri);
+                        println!("Added {} ({}) at {}", name, language.name(), uri);
+                    }
+                    Err(error) => fail!("printer: {}", error),
+                }
+            }
+            [command, name] if ["remove", "pause", "resume", "default"].contains(command) => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("printer");
+                }
+                let mut subsystem = printing::get_subsystem().write();
+                let Some(subsystem) = subsystem.as_mut() else {
+                    return fail!("printer: printing is not running");
+                };
+                let Some(printer) = subsystem.find_printer(name) else {
+                    return fail!("printer: no printer '{}'", name);
+                };
+                let result = match *command {
+                    "remove" => subsystem.remove_printer(printer.id).map(|()| {
+                        crate::drivers::printing::print_remove_printer(&printer.name);
+                    }),
+                    "pause" => subsystem.pause_printer(printer.id),
+                    "resume" => subsystem.resume_printer(printer.id),
+                    _ => subsystem.set_default_printer(printer.id),
+                };
+                if let Err(error) = result {
+                    fail!("printer: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_scan(&self, args: &[&str]) {
+        use crate::scanning::{self, ImageFormat, ScanMode, ScanSettings, ScanSource};
+
+        const USAGE: &str =
+            "scan [/d:scanner] [/r:dpi] [/m:color|gray|lineart] [/s:flatbed|adf|duplex] [/f:raw|jpeg|pdf|png] [dir]";
+        let (mut scanner, mut settings, mut directory) = (None, ScanSettings::default(), None);
+        for &arg in args {
+            let (option, value) = match arg.split_once(':') {
+                Some((option, value)) if option.starts_with('/') => (option.to_ascii_lowercase(), value),
+                _ if directory.is_none() => {
+                    directory = Some(arg);
+                    continue;
+                }
+                _ => return usage(USAGE),
+            };
+            match option.as_str() {
+                "/d" => scanner = Some(value),
+                "/r" => match value.parse::<u32>() {
+                    Ok(dpi @ 1..=9600) => settings.resolution = dpi,
+                    _ => return usage(USAGE),
+                },
+                "/m" => match ScanMode::parse(value) {
+                    Some(mode) => settings.mode = mode,
+                    None => return usage(USAGE),
+                },
+                "/s" => match ScanSource::parse(value) {
+                    Some(source) => settings.source = source,
+                    None => return usage(USAGE),
+                },
+                "/f" => match ImageFormat::parse(value) {
+                    Some(format) => settings.format = format,
+                    None => return usage(USAGE),
+                },
+                _ => return usage(USAGE),
+            }
+        }
+        settings.multi_page = settings.source != ScanSource::Flatbed;
+        let directory = match directory {
+            Some(directory) => String::from(directory),
+            None => String::from(scanning::DEFAULT_DIRECTORY),
+        };
+
+        let mut subsystem = scanning::get_subsystem().write();
+        let Some(subsystem) = subsystem.as_mut() else {
+            return fail!("scan: scanning is not running");
+        };
+        // Without /d, the first scanner ready to take a job
+        let scanner = match scanner {
+            Some(name) => subsystem.find_scanner(name),
+            None => subsystem.list_scanners().into_iter().find(|s| s.status == scanning::ScannerStatus::Idle),
+        };
+        let Some(scanner) = scanner else {
+            return fail!("scan: no such scanner; 'scanner' lists them");
+        };
+        let user = accounts::token_user_name(crate::process::current_token()).unwrap_or_default();
+        match subsystem.start_scan(scanner.id, settings, &user, &directory) {
+            Ok(job) => println!("Scan job {} started on {}; pages go to {}", job, scanner.name, directory),
+            Err(error) => fail!("scan: {}", error),
+        }
+    }
+
+    fn cmd_scanner(&self, args: &[&str]) {
+        use crate::scanning;
+
+        const USAGE: &str = "scanner [jobs | info name | cancel job | add name uri | remove name]";
+        match args {
+            [] => {
+                let subsystem = scanning::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                println!("{:<20} {:<6} {:<10} {}", "Scanner", "Type", "Status", "URI");
+                for scanner in subsystem.list_scanners() {
+                    println!(
+                        "{:<20} {:<6} {:<10} {}",
+                        scanner.name,
+                        scanner.device_type,
+                        format!("{:?}", scanner.status),
+                        scanner.uri
+                    );
+                }
+            }
+            ["jobs"] => {
+                let subsystem = scanning::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                println!("{:>5} {:<16} {:<12} {:<10} {:>5}  {}", "Job", "Scanner", "User", "Status", "Pages", "Files");
+                for job in subsystem.list_jobs() {
+                    let scanner = subsystem.get_scanner(job.scanner_id).map_or(String::from("?"), |s| s.name);
+                    println!(
+                        "{:>5} {:<16} {:<12} {:<10} {:>5}  {}",
+                        job.id,
+                        scanner,
+                        job.user,
+                        format!("{:?}", job.status),
+                        job.pages_scanned,
+                        job.output_files.join(" ")
+                    );
+                    if let Some(error) = &job.error_message {
+                        println!("{:>5} {}", "", error);
+                    }
+                }
+            }
+            ["info", name] => {
+                let subsystem = scanning::get_subsystem().read();
+                let Some(subsystem) = subsystem.as_ref() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                let Some(scanner) = subsystem.find_scanner(name) else {
+                    return fail!("scanner: no scanner '{}'", name);
+                };
+                let capabilities = &scanner.capabilities;
+                let names = |names: Vec<&str>| names.join(", ");
+                println!("{} ({} {}), {:?}", scanner.name, scanner.vendor, scanner.model, scanner.status);
+                println!("  URI:         {}", scanner.uri);
+                println!("  Sources:     {}", names(capabilities.sources.iter().map(|s| s.name()).collect()));
+                println!("  Modes:       {}", names(capabilities.modes.iter().map(|m| m.name()).collect()));
+                let resolutions: Vec<String> = capabilities.resolutions.iter().map(|r| r.to_string()).collect();
+                println!("  Resolutions: {} dpi", resolutions.join(", "));
+                println!("  Formats:     {}", names(capabilities.formats.iter().map(|f| f.name()).collect()));
+                println!("  Area:        {:.0} x {:.0} mm", capabilities.max_width, capabilities.max_height);
+            }
+            ["cancel", job] => {
+                let Ok(job) = job.parse::<u32>() else {
+                    return usage("scanner cancel job");
+                };
+                let mut subsystem = scanning::get_subsystem().write();
+                let Some(subsystem) = subsystem.as_mut() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                let Some(found) = subsystem.get_job(job).filter(|j| j.status == scanning::ScanJobStatus::InProgress)
+                else {
+                    return fail!("scanner: no job {} scanning", job);
+                };
+                let user = accounts::token_user_name(crate::process::current_token());
+                if user.as_deref() != Some(found.user.as_str()) && !accounts::caller_is_admin() {
+                    return access_denied("scanner");
+                }
+                match subsystem.cancel_scan(job) {
+                    Ok(()) => println!("Scan job {} cancelled", job),
+                    Err(error) => fail!("scanner: {}", error),
+                }
+            }
+            ["add", name, uri] => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("scanner");
+                }
+                let mut subsystem = scanning::get_subsystem().write();
+                let Some(subsystem) = subsystem.as_mut() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                match subsystem.add_network_scanner(name, uri) {
+                    Ok(_) => println!("Added {} at {}; 'scanner info {}' shows what it can do", name, uri, name),
+                    Err(error) => fail!("scanner: {}", error),
+                }
+            }
+            ["remove", name] => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("scanner");
+                }
+                let mut subsystem = scanning::get_subsystem().write();
+                let Some(subsystem) = subsystem.as_mut() else {
+                    return fail!("scanner: scanning is not running");
+                };
+                let Some(scanner) = subsystem.find_scanner(name) else {
+                    return fail!("scanner: no scanner '{}'", name);
+                };
+                if let Err(error) = subsystem.remove_scanner(scanner.id) {
+                    fail!("scanner: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_vnc(&self, args: &[&str]) {
+        use crate::vnc;
+
+        const USAGE: &str = "vnc [start [port] | stop | password text | disconnect n]";
+        if !args.is_empty() && !accounts::caller_is_admin() {
+            return access_denied("vnc");
+        }
+        match args {
+            [] => {
+                let Some(status) = vnc::status() else {
+                    let password = if vnc::has_password() { "set" } else { "not set" };
+                    return println!("VNC server stopped; password {}", password);
+                };
+                println!("VNC server listening on port {}", status.port);
+                if status.clients.is_empty() {
+                    return println!("No clients connected");
+                }
+                println!(
+                    "{:>3} {:<21} {:>8} {:<8} {:<24} {:>9}",
+                    "Id", "Address", "Time", "Encoding", "Pixels", "Sent"
+                );
+                let now = crate::time::clocksource::now_ns();
+                for client in status.clients {
+                    let (encoding, pixels) = if client.authenticated {
+                        (client.encoding, client.pixel_format)
+                    } else {
+                        ("-", String::from("logging in"))
+                    };
+                    println!(
+                        "{:>3} {:<21} {:>7}s {:<8} {:<24} {:>9}",
+                        client.id,
+                        client.address,
+                        now.saturating_sub(client.connected_ns) / 1_000_000_000,
+                        encoding,
+                        pixels,
+                        human_size(client.bytes_sent)
+                    );
+                }
+            }
+            ["start"] | ["start", _] => {
+                let port = match args.get(1).map(|port| port.parse::<u16>()) {
+                    None => vnc::DEFAULT_PORT,
+                    Some(Ok(port)) if port != 0 => port,
+                    Some(_) => return usage("vnc start [port]"),
+                };
+                match vnc::start(port) {
+                    Ok(()) => println!("VNC server listening on port {}", port),
+                    Err(error) => fail!("vnc: {}", error),
+                }
+            }
+            ["stop"] => {
+                if let Err(error) = vnc::stop() {
+                    fail!("vnc: {}", error);
+                }
+            }
+            ["password", password] => match vnc::set_password(password) {
+                Ok(()) if password.len() > 8 => println!("Only the first 8 characters are used"),
+                Ok(()) => {}
+                Err(error) => fail!("vnc: {}", error),
+            },
+            ["disconnect", id] => {
+                let Ok(id) = id.parse::<u32>() else {
+                    return usage("vnc disconnect n");
+                };
+                if let Err(error) = vnc::disconnect(id) {
+                    fail!("vnc: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_rdp(&self, args: &[&str]) {
+        use crate::rdp;
+
+        const USAGE: &str = "rdp [start [port] | stop | disconnect n]";
+        if !args.is_empty() && !accounts::caller_is_admin() {
+            return access_denied("rdp");
+        }
+        match args {
+            [] => {
+                let Some(status) = rdp::status() else {
+                    return println!("RDP server stopped");
+                };
+                println!("RDP server listening on port {}", status.port);
+                if status.clients.is_empty() {
+                    return println!("No clients connected");
+                }
+                println!(
+                    "{:>3} {:<21} {:>8} {:<12} {:<15} {:>5} {:>7} {:>9}",
+                    "Id", "Address", "Time", "User", "Computer", "Depth", "Key", "Sent"
+                );
+                let now = crate::time::clocksource::now_ns();
+                for client in status.clients {
+                    let encryption = match client.encryption {
+                        Some(bits) => format!("{}-bit", bits),
+                        None => String::from("-"),
+                    };
+                    println!(
+                        "{:>3} {:<21} {:>7}s {:<12} {:<15} {:>5} {:>7} {:>9}",
+                        client.id,
+                        client.address,
+                        now.saturating_sub(client.connected_ns) / 1_000_000_000,
+                        client.user.as_deref().unwrap_or("logging in"),
+                        client.name,
+                        client.bpp,
+                        encryption,
+                        human_size(client.bytes_sent)
+                    );
+                }
+            }
+            ["start"] | ["start", _] => {
+                let port = match args.get(1).map(|port| port.parse::<u16>()) {
+                    None => rdp::DEFAULT_PORT,
+                    Some(Ok(port)) if port != 0 => port,
+                    Some(_) => return usage("rdp start [port]"),
+                };
+                match rdp::start(port) {
+                    Ok(()) => println!("RDP server listening on port {}", port),
+                    Err(error) => fail!("rdp: {}", error),
+                }
+            }
+            ["stop"] => {
+                if let Err(error) = rdp::stop() {
+                    fail!("rdp: {}", error);
+                }
+            }
+            ["disconnect", id] => {
+                let Ok(id) = id.parse::<u32>() else {
+                    return usage("rdp disconnect n");
+                };
+                if let Err(error) = rdp::disconnect(id) {
+                    fail!("rdp: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_http(&self, args: &[&str]) {
+        use crate::net::http::{self, client::Request, server, Action, Url};
+
+        const USAGE: &str = "http [get url file | cancel n | serve [port] | stop]";
+        match args {
+            [] => {
+                match server::status() {
+                    Some(status) => println!(
+                        "HTTP server listening on port {}, {} connections, {} requests served",
+                        status.port,
+                        status.connections.len(),
+                        status.requests
+                    ),
+                    None => println!("HTTP server stopped"),
+                }
+                let transfers = http::transfers();
+                if transfers.is_empty() {
+                    return println!("No transfers");
+                }
+                println!("{:>3} {:>8} {:>9}  {}", "Id", "Time", "Received", "URL");
+                let now = crate::time::clocksource::now_ns();
+                for transfer in transfers {
+                    let target = match transfer.action {
+                        Action::Save(path) => format!(" -> {}", path),
+                        Action::SetClock => String::from(" -> clock"),
+                        Action::Discard => String::new(),
+                    };
+                    println!(
+                        "{:>3} {:>7}s {:>9}  {}{}",
+                        transfer.id,
+                        now.saturating_sub(transfer.started_ns) / 1_000_000_000,
+                        human_size(transfer.received as u64),
+                        transfer.url,
+                        target
+                    );
+                }
+            }
+            ["get", url, path] => {
+                let url = match Url::parse(url) {
+                    Ok(url) => url,
+                    Err(error) => return fail!("http: {}", error),
+                };
+                // The file is made now, so that a caller who may not write
+                // it finds out before the transfer starts
+                if crate::fs::vfs::VFS.lock().write_file(path, &[]).is_err() {
+                    return fail!("http: cannot write '{}'", path);
+                }
+                let id = http::spawn(Request::get(url), Action::Save(path.to_string()));
+                println!("Transfer {} started", id);
+            }
+            ["cancel", id] => {
+                let Ok(id) = id.parse::<u32>() else {
+                    return usage("http cancel n");
+                };
+                if let Err(error) = http::cancel(id) {
+                    fail!("http: {}", error);
+                }
+            }
+            ["serve"] | ["serve", _] => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("http");
+                }
+                let port = match args.get(1).map(|port| port.parse::<u16>()) {
+                    None => server::DEFAULT_PORT,
+                    Some(Ok(port)) if port != 0 => port,
+                    Some(_) => return usage("http serve [port]"),
+                };
+                match server::start(port) {
+                    Ok(()) => println!("HTTP server listening on port {}", port),
+                    Err(error) => fail!("http: {}", error),
+                }
+            }
+            ["stop"] => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("http");
+                }
+                if let Err(error) = server::stop() {
+                    fail!("http: {}", error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_mount(&self, args: &[&str]) {
+        use crate::fs::ninep;
+
+        match args {
+            [] => {
+                for (mount_point, fs_type, _) in VFS.lock().mounts() {
+                    println!("{:<8} {}", fs_type, mount_point);
+                }
+                for (tag, mounted) in ninep::virtio::shares() {
+                    println!("9P share \"{}\"{}", tag, if mounted { ", mounted" } else { "" });
+                }
+            }
+            ["9p", source, directory] => {
+                if !accounts::caller_is_admin() {
+                    return access_denied("mount");
+                }
+                if !directory.starts_with('/') || *directory == "/" {
+                    return fail!("mount: '{}' is not a directory to mount on", directory);
+                }
+                if let Err(error) = ninep::mount(source, directory.trim_end_matches('/')) {
+                    fail!("mount: {}: {:?}", source, error);
+                }
+            }
+            _ => usage("mount [9p tag|tcp:host[:port][/aname] dir]"),
+        }
+    }
+
+    fn cmd_umount(&self, args: &[&str]) {
+        let [directory] = args else {
+            return usage("umount dir");
+        };
+        if !accounts::caller_is_admin() {
+            return access_denied("umount");
+        }
+        let directory = directory.trim_end_matches('/');
+        if directory.is_empty() {
+            return fail!("umount: the root cannot be unmounted");
+        }
+        if !VFS.lock().unmount(directory) {
+            fail!("umount: nothing mounted on {}", directory);
+        }
+    }
+
+    fn cmd_taskset(&self, args: &[&str]) {
+        use crate::process::{smp_scheduler, ThreadId};
+
+        let (tid, mask) = match args {
+            [tid] => (tid, None),
+            [tid, mask] => (tid, Some(mask)),
+            _ => {
+                usage("taskset tid [mask]");
+                return;
+            }
+        };
+        let Ok(tid) = tid.parse::<u32>() else {
+            fail!("taskset: thread id must be a number");
+            return;
+        };
+        let tid = ThreadId(tid);
+        if crate::process::thread::THREAD_MANAGER.lock().get_thread(tid).is_none() {
+            fail!("taskset: no thread {}", tid.0);
+            return;
+        }
+
+        if let Some(mask) = mask {
+            if !accounts::caller_is_admin() {
+                access_denied("taskset");
+                return;
+            }
+            let Ok(mask) = u64::from_str_radix(mask.trim_start_matches("0x"), 16) else {
+                fail!("taskset: mask must be hexadecimal");
+                return;
+            };
+            if !smp_scheduler::set_thread_cpu_affinity(tid, mask) {
+                fail!("taskset: no online CPU in mask {:#x}", mask);
+                return;
+            }
+        }
+        println!("Thread {} affinity mask: {:#x}", tid.0, smp_scheduler::get_thread_cpu_affinity(tid));
+    }
+
+    fn cmd_ionice(&self, args: &[&str]) {
+        use crate::drivers::block::{self, IoPriority};
+
+        let (pid, priority) = match args {
+            [pid] => (pid, None),
+            [pid, priority] => (pid, Some(priority)),
+            _ => {
+                usage("ionice pid [rt/n|be/n|idle]");
+                return;
+            }
+        };
+        let Ok(pid) = pid.parse::<u32>() else {
+            fail!("ionice: pid must be a number");
+            return;
+        };
+        if crate::process::executor::EXECUTOR.lock().get_process(pid).is_none() {
+            fail!("ionice: no process {}", pid);
+            return;
+        }
+
+        if let Some(priority) = priority {
+            if !accounts::caller_is_admin() {
+                access_denied("ionice");
+                return;
+            }
+            let Ok(priority) = priority.parse::<IoPriority>() else {
+                fail!("ionice: priority must be rt/0-7, be/0-7 or idle");
+                return;
+            };
+            block::set_priority(pid, priority);
+        }
+        println!("Process {}: {}", pid, block::priority(pid));
+    }
+
+    fn cmd_serial(&self, args: &[&str]) {
+        use crate::serial;
+
+        match args {
+            [] => {}
+            [index, baud, flow @ ..] if flow.len() <= 1 => {
+                if !accounts::caller_is_admin() {
+                    access_denied("serial");
+                    return;
+                }
+                let Some(port) = index.parse().ok().and_then(serial::port) else {
+                    fail!("serial: no port ttyS{}", index);
+                    return;
+                };
+                let flow_control = match flow {
+                    [] => port.flow_control(),
+                    ["rtscts"] => true,
+                    ["none"] => false,
+                    _ => {
+                        fail!("serial: flow control must be 'rtscts' or 'none'");
+                        return;
+                    }
+                };
+                match baud.parse::<u32>() {
+                    Ok(baud) if (1..=serial::uart::BASE_BAUD).contains(&baud) => {
+                        port.set_baud(baud);
+                        port.set_flow_control(flow_control);
+                    }
+                    _ => fail!("serial: baud rate must be 1 to {}", serial::uart::BASE_BAUD),
+                }
+                return;
+            }
+            _ => {
+                usage("serial [n baud [rtscts|none]]");
+                return;
+            }
+        }
+
+        for info in serial::port_info() {
+            let registers = match info.registers {
+                serial::uart::Registers::Port(base) => format!("io {:#x}", base),
+                serial::uart::Registers::Mmio { base, .. } => format!("mmio {:#x}", base),
+            };
+            let owner = match info.owner {
+                serial::Owner::Free if info.index == serial::CONSOLE_PORT => String::from("console"),
+                serial::Owner::Free => String::from("free"),
+                serial::Owner::Debugger => String::from("debugger"),
+                serial::Owner::Bluetooth => String::from("bluetooth"),
+                serial::Owner::Process(pid) => format!("pid {}", pid),
+            };
+            let irq = info.irq.map_or(String::from("polled"), |irq| format!("irq {}", irq));
+            println!(
+                "ttyS{}: {} {}, {}, {} baud{}, {}",
+                info.index,
+                info.kind,
+                registers,
+                irq,
+                info.baud,
+                if info.flow_control { " rtscts" } else { "" },
+                owner
+            );
+            println!(
+                "  rx {} tx {} bytes, {} interrupts, {} overruns, {} dropped, {}/{} queued",
+                info.stats.rx_bytes,
+                info.stats.tx_bytes,
+                info.stats.interrupts,
+                info.stats.overruns,
+                info.stats.dropped,
+                info.rx_queued,
+                info.tx_queued
+            );
+        }
+        for info in crate::usb::serial::ports().filter_map(|port| port.info()) {
+            let owner = match info.owner {
+                serial::Owner::Free => String::from("free"),
+                serial::Owner::Debugger => String::from("debugger"),
+                serial::Owner::Bluetooth => String::from("bluetooth"),
+                serial::Owner::Process(pid) => format!("pid {}", pid),
+            };
+            println!(
+                "{}{}: {} at USB address {}, {} baud{}, {}",
+                info.name,
+                info.index,
+                info.chip.name(),
+                info.address,
+                info.baud,
+                if info.flow_control { " rtscts" } else { "" },
+                owner
+            );
+            println!(
+                "  rx {} tx {} bytes, {} errors, {} dropped, {}/{} queued",
+                info.stats.rx_bytes,
+                info.stats.tx_bytes,
+                info.stats.errors,
+                info.stats.dropped,
+                info.rx_queued,
+                info.tx_queued
+            );
+        }
+    }
+
+    fn cmd_dmesg(&self, args: &[&str]) {
+        use crate::klog::{self, Console, Level};
+
+        const USAGE: &str = "dmesg [-c|-C] [-l level] [-n level] [count]";
+        let (mut clear, mut print_lines) = (false, true);
+        let mut max_level = Level::Debug;
+        let mut console_level = None;
+        let mut count = usize::MAX;
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "-c" => clear = true,
+                "-C" => (clear, print_lines) = (true, false),
+                "-l" => match args.next().and_then(|level| Level::parse(level)) {
+                    Some(level) => max_level = level,
+                    None => {
+                        fail!("dmesg: level must be 0 to 7 or emerg, alert, crit, err, warn, notice, info, debug");
+                        return;
+                    }
+                },
+                // As on Linux, -n 1 and -n emerg both leave only emergencies
+                "-n" => match args.next().map(|level| (level.parse::<u8>(), Level::parse(level))) {
+                    Some((Ok(level @ klog::CONSOLE_LEVEL_MIN..=klog::CONSOLE_LEVEL_MAX), _)) => {
+                        console_level = Some(level)
+                    }
+                    Some((Err(_), Some(level))) => console_level = Some(level as u8 + 1),
+                    _ => {
+                        fail!("dmesg: console level must be 1 to 8 or a level name");
+                        return;
+                    }
+                },
+                _ => match arg.parse() {
+                    Ok(n) => count = n,
+                    Err(_) => {
+                        usage(USAGE);
+                        return;
+                    }
+                },
+            }
+        }
+        if (clear || console_level.is_some()) && !accounts::caller_is_admin() {
+            access_denied("dmesg");
+            return;
+        }
+
+        if print_lines {
+            let lines = klog::lines(max_level, false);
+            for line in &lines[lines.len().saturating_sub(count)..] {
+                print!("{}", String::from_utf8_lossy(line));
+            }
+        }
+        if clear {
+            klog::clear();
+        }
+        if let Some(level) = console_level {
+            Console::Serial.set_level(level);
+        }
+    }
+
+    fn cmd_cmdline(&self, args: &[&str]) {
+        use crate::boot::cmdline::{self, Source};
+
+        let verbose = match args {
+            [] => false,
+            ["-v"] => true,
+            _ => {
+                usage("cmdline [-v]");
+                return;
+            }
+        };
+        println!("{}", cmdline::raw());
+        if !verbose {
+            return;
+        }
+        for option in cmdline::effective() {
+            let source = match option.source {
+                Source::Default => "default",
+                Source::CommandLine => "command line",
+                Source::Invalid => "invalid value ignored",
+            };
+            println!("  {:<30} {:<12} ({}) {}", option.name, option.value, source, option.help);
+        }
+        for word in cmdline::unrecognized() {
+            println!("  {:<30} not used by the kernel", word);
+        }
+    }
+
+    fn cmd_lsdev(&self, args: &[&str]) {
+        use crate::driver::{DeviceId, DeviceState, DEVICES};
+
+        let verbose = match args {
+            [] => false,
+            ["-v"] => true,
+            _ => {
+                usage("lsdev [-v]");
+                return;
+            }
+        };
+        let devices = DEVICES.lock();
+        let mut stack: Vec<(usize, DeviceId)> = devices.roots().map(|device| (0, device.id)).collect();
+        stack.reverse();
+        while let Some((depth, id)) = stack.pop() {
+            let Some(device) = devices.get(id) else { continue };
+            let binding = match (device.driver, device.state) {
+                (Some(driver), _) => String::from(driver),
+                (None, DeviceState::Unbound) => String::from("-"),
+                (None, DeviceState::Deferred(reason) | DeviceState::Failed(reason)) => {
+                    format!("({}: {})", device.state.name(), reason)
+                }
+                (None, state) => format!("({})", state.name()),
+            };
+            let name = format!("{:width$}{}", "", device.name, width = depth * 2);
+            if verbose {
+                println!("{:<32} {:<8} {:<24} {}", name, device.bus.name(), binding, device.ident.modalias());
+            } else {
+                println!("{:<32} {}", name, binding);
+            }
+            stack.extend(device.children.iter().rev().map(|child| (depth + 1, *child)));
+        }
+    }
+
+    fn cmd_pnp(&self, args: &[&str]) {
+        use crate::driver::{pnp, DeviceId, Ident, DEVICES};
+
+        match args {
+            [] => {}
+            [action @ ("remove" | "disable" | "enable"), path] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("pnp");
+                    return;
+                }
+                let Some(device) = DEVICES.lock().lookup(path).cloned() else {
+                    fail!("pnp: {}: no such device", path);
+                    return;
+                };
+                if device.ident == Ident::Root {
+                    fail!("pnp: {}: a bus root cannot be removed or disabled", path);
+                    return;
+                }
+                let result = match *action {
+                    "remove" => pnp::query_remove(&[device.id]),
+                    action => pnp::set_enabled(device.id, action == "enable"),
+                };
+                if let Err(e) = result {
+                    fail!("pnp: {} {}: {}", action, path, e);
+                }
+                return;
+            }
+            _ => {
+                usage("pnp [remove|disable|enable path]");
+                return;
+            }
+        }
+
+        let devices: Vec<(String, &'static str, DeviceId)> = {
+            let devices = DEVICES.lock();
+            devices
+                .iter()
+                .filter(|device| device.ident != Ident::Root)
+                .map(|device| (devices.path(device.id), device.state.name(), device.id))
+                .collect()
+        };
+        for (path, state, id) in devices {
+            let resources: Vec<String> = pnp::resources(id).iter().map(|resource| resource.to_string()).collect();
+            println!("{:<36} {:<9} {}", path, state, resources.join(", "));
+        }
+    }
+
+    fn cmd_pcie(&self, args: &[&str]) {
+        use crate::driver::bus::pci_name;
+        use crate::pcie::{aer, hotplug};
+
+        match args {
+            [] => {}
+            ["slot", number, state @ ("on" | "off")] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("pcie");
+                    return;
+                }
+                let Ok(number) = number.parse() else {
+                    fail!("pcie: slot must be a number");
+                    return;
+                };
+                if let Err(e) = hotplug::set_slot_power(number, *state == "on") {
+                    fail!("pcie: slot {}: {}", number, e);
+                }
+                return;
+            }
+            _ => {
+                usage("pcie [slot n on|off]");
+                return;
+            }
+        }
+
+        let slots = hotplug::slots();
+        if slots.is_empty() {
+            println!("No hot-plug slots");
+        }
+        for slot in &slots {
+            let (secondary, _) = slot.buses();
+            println!("slot {:<4} {}  bus {:02x}  {}", slot.number, pci_name(&slot.port), secondary, slot.state.name());
+        }
+        let counters = aer::counters();
+        if counters.is_empty() {
+            println!("No PCIe errors reported");
+            return;
+        }
+        println!("{:<14} {:>11} {:>9} {:>6} {:>7}", "function", "correctable", "nonfatal", "fatal", "resets");
+        for c in counters {
+            println!(
+                "{:<14} {:>11} {:>9} {:>6} {:>7}",
+                pci_name(&c.location),
+                c.correctable,
+                c.nonfatal,
+                c.fatal,
+                c.resets
+            );
+        }
+    }
+
+    fn cmd_ksm(&self, args: &[&str]) {
+        use crate::memory::ksm;
+
+        match args {
+            [] => {}
+            [state @ ("on" | "off")] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("ksm");
+                    return;
+                }
+                ksm::set_running(*state == "on");
+            }
+            _ => {
+                usage("ksm [on|off]");
+                return;
+            }
+        }
+
+        let stats = ksm::stats();
+        println!("Same-page merging: {}", if ksm::running() { "on" } else { "off" });
+        println!("  Merged frames:   {}", stats.pages_shared);
+        println!("  Pages saved:     {}", stats.pages_sharing);
+        println!("  Awaiting a twin: {}", stats.pages_unshared);
+        println!("  Volatile:        {}", stats.pages_volatile);
+        println!("  Full scans:      {}", stats.full_scans);
+    }
+
+    fn cmd_wdm(&self, args: &[&str]) {
+        use crate::nt::wdm;
+
+        match args {
+            [] => {}
+            ["load", path] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("wdm");
+                    return;
+                }
+                match wdm::load(path) {
+                    Ok(name) => println!("Loaded {}", name),
+                    Err(e) => fail!("wdm: {}: {}", path, e),
+                }
+                return;
+            }
+            ["unload", name] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("wdm");
+                    return;
+                }
+                if let Err(e) = wdm::unload(name) {
+                    fail!("wdm: {}: {}", name, e);
+                }
+                return;
+            }
+            _ => {
+                usage("wdm [load file.sys | unload name]");
+                return;
+            }
+        }
+
+        let drivers = wdm::list();
+        if drivers.is_empty() {
+            println!("No Windows drivers loaded");
+        }
+        for driver in drivers {
+            println!("{} ({})", driver.name, driver.path);
+            for device in driver.devices {
+                let name = device.name.as_deref().unwrap_or("(unnamed)");
+                println!("  {:24} {:8} {} open", name, device.platform, device.opens);
+                if let Some(lower) = device.attached_to {
+                    println!("    attached to {}", lower);
+                }
+                for link in device.links {
+                    println!("    -> {}", link);
+                }
+            }
+        }
+        let (blocks, bytes) = wdm::ntoskrnl::pool_usage();
+        println!("Pool: {} blocks, {} bytes", blocks, bytes);
+    }
+
+    fn cmd_mkswap(&self, args: &[&str]) {
+        let [target] = args else {
+            usage("mkswap diskN|diskNpM|file");
+            return;
+        };
+        if !accounts::caller_is_admin() {
+            access_denied("mkswap");
+            return;
+        }
+        match crate::memory::swap::mkswap(target) {
+            Ok(pages) => println!("Swap space on {}: {} KB", target, pages as u64 * 4),
+            Err(e) => fail!("mkswap: {}: {}", target, e),
+        }
+    }
+
+    fn cmd_swapon(&self, args: &[&str]) {
+        use crate::memory::swap;
+
+        match args {
+            [] => {
+                let areas = swap::areas();
+                if areas.is_empty() {
+                    println!("No swap areas");
+                    return;
+                }
+                println!("{:<24} {:<10} {:>10} {:>10} {:>5}", "NAME", "TYPE", "SIZE", "USED", "PRIO");
+                for area in areas {
+                    println!(
+                        "{:<24} {:<10} {:>9}K {:>9}K {:>5}",
+                        area.name,
+                        if area.is_file { "file" } else { "partition" },
+                        area.pages as u64 * 4,
+                        area.used as u64 * 4,
+                        area.priority
+                    );
+                }
+            }
+            [target] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("swapon");
+                    return;
+                }
+                if let Err(e) = swap::swapon(target) {
+                    fail!("swapon: {}: {}", target, e);
+                }
+            }
+            _ => usage("swapon [target]"),
+        }
+    }
+
+    fn cmd_swapoff(&self, args: &[&str]) {
+        let [target] = args else {
+            usage("swapoff target");
+            return;
+        };
+        if !accounts::caller_is_admin() {
+            access_denied("swapoff");
+            return;
+        }
+        if let Err(e) = crate::memory::swap::swapoff(target) {
+            fail!("swapoff: {}: {}", target, e);
+        }
+    }
+
+    fn cmd_oom(&self, args: &[&str]) {
+        use crate::memory::oom::{self, OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN};
+
+        match args {
+            [] => {
+                println!("{:>5} {:<16} {:>8} {:>6} {:>8}", "PID", "NAME", "PAGES", "ADJ", "SCORE");
+                for score in oom::report() {
+                    let points = score.points.map_or(String::from("-"), |points| format!("{}", points));
+                    println!("{:>5} {:<16} {:>8} {:>6} {:>8}", score.pid, score.name, score.pages, score.adj, points);
+                }
+            }
+            [pid, adj] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("oom");
+                    return;
+                }
+                let (Ok(pid), Ok(adj)) = (pid.parse::<u32>(), adj.parse::<i16>()) else {
+                    fail!("oom: pid and adjustment must be numbers");
+                    return;
+                };
+                if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&adj) {
+                    fail!("oom: adjustment must be from -1000 to 1000");
+                    return;
+                }
+                if !crate::process::executor::EXECUTOR.lock().set_oom_score_adj(pid, adj) {
+                    fail!("oom: no process {}", pid);
                 }
             }
+            _ => usage("oom [pid adj]"),
         }
     }
 
-    fn execute_command(&mut self) {
-        let command = self.command_buffer.trim();
-        
-        if command.is_empty() {
+    fn cmd_passwd(&self, args: &[&str]) {
+        let LoginState::LoggedIn { user, .. } = &self.login else {
             return;
+        };
+        // passwd old new, or passwd name old|- new
+        let (name, old, new) = match args {
+            [old, new] => (user.as_str(), *old, *new),
+            [name, old, new] => (*name, *old, *new),
+            _ => {
+                usage("passwd [name] old|- new");
+                return;
+            }
+        };
+        let old = if old == "-" { None } else { Some(old) };
+        match accounts::change_password(name, old, new) {
+            Ok(()) => println!("Password changed for {}", name),
+            Err(status) => fail!("passwd: {:?}", status),
         }
+    }
 
-        // Parse and execute command
-        let parts: Vec<&str> = command.split_whitespace().collect();
-        if parts.is_empty() {
-            return;
-        }
+    fn cmd_clear(&self) {
+        // Clear screen using VGA buffer clear
+        crate::vga_buffer::clear_screen();
+        // Re-show the shell header
+        self.print_welcome();
+    }
 
-        match parts[0] {
-            "help" => self.cmd_help(),
-            "clear" | "cls" => self.cmd_clear(),
-            "echo" => self.cmd_echo(&parts[1..]),
-            "ver" | "version" => self.cmd_version(),
-            "mem" | "memory" => self.cmd_memory(),
-            "ps" | "processes" => self.cmd_processes(),
-            "uptime" => self.cmd_uptime(),
-            "ls" | "dir" => self.cmd_ls(&parts[1..]),
-            "cat" | "type" => self.cmd_cat(&parts[1..]),
-            "shutdown" => self.cmd_shutdown(),
-            "reboot" => self.cmd_reboot(),
-            "test" => self.cmd_test(),
-            "exec" | "run" => self.cmd_execute(&parts[1..]),
+    fn cmd_echo(&mut self, args: &[&str]) {
+        match args {
+            [] => println!("ECHO is {}", if self.echo { "on" } else { "off" }),
+            [word] if word.eq_ignore_ascii_case("on") => self.echo = true,
+            [word] if word.eq_ignore_ascii_case("off") => self.echo = false,
             _ => {
-                // Try to execute as a binary if it ends with .exe
-                if parts[0].ends_with(".exe") || parts[0].ends_with(".EXE") {
-                    self.cmd_execute(&parts[0..]);
-                } else {
-                    println!("Unknown command: '{}'. Type 'help' for available commands.", parts[0]);
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        print!(" ");
+                    }
+                    print!("{}", arg);
                 }
+                println!();
             }
         }
     }
 
-    fn cmd_help(&self) {
-        println!("Available commands:");
-        println!("  help          - Show this help message");
-        println!("  clear/cls     - Clear the screen");
-        println!("  echo [text]   - Print text to screen");
-        println!("  ver/version   - Show system version");
-        println!("  mem/memory    - Show memory usage");
-        println!("  ps/processes  - List running processes");
-        println!("  uptime        - Show system uptime");
-        println!("  ls/dir [path] - List directory contents");
-        println!("  cat/type file - Display file contents");
-        println!("  exec/run file - Execute a Windows .exe file");
-        println!("  test          - Run system tests");
-        println!("  shutdown      - Shutdown the system");
-        println!("  reboot        - Reboot the system");
-        println!("\nYou can also run .exe files directly: hello.exe");
+    // color [bf]: background and foreground as hex digits, as cmd.exe's;
+    // without them, the console's own colours
+    fn cmd_color(&self, args: &[&str]) {
+        use crate::win32::console::{CONSOLE_MANAGER, STD_OUTPUT_HANDLE};
+
+        let attribute = match args {
+            [] => None,
+            [digits] if digits.len() == 2 => match u8::from_str_radix(digits, 16) {
+                Ok(attribute) => Some(attribute),
+                Err(_) => return usage("color [bf]"),
+            },
+            _ => return usage("color [bf]"),
+        };
+        if attribute.is_some_and(|attribute| attribute >> 4 == attribute & 0x0F) {
+            return fail!("color: the background and foreground are the same");
+        }
+
+        let mut manager = CONSOLE_MANAGER.lock();
+        let output = manager.get_std_handle(STD_OUTPUT_HANDLE);
+        let set = match attribute {
+            Some(attribute) => manager.set_console_text_attribute(output, attribute as u16),
+            None => manager.reset_text_attribute(output),
+        };
+        let attribute = manager.screen_buffer_info(output).map(|info| info.attributes);
+        drop(manager);
+        match attribute {
+            // Text the shell echoes itself goes straight to the screen
+            Some(attribute) if set => crate::vga_buffer::set_attribute(attribute as u8),
+            _ => fail!("color: there is no console"),
+        }
     }
 
-    fn cmd_clear(&self) {
-        // Clear screen using VGA buffer clear
-        crate::vga_buffer::clear_screen();
-        // Re-show the shell header
-        self.print_welcome();
+    // title [text]: show or set the console's title
+    fn cmd_title(&self, args: &[&str]) {
+        use crate::win32::console::CONSOLE_MANAGER;
+
+        if args.is_empty() {
+            let title = CONSOLE_MANAGER.lock().get_console_title();
+            match title {
+                Some(title) => println!("{}", title),
+                None => fail!("title: there is no console"),
+            }
+        } else if !CONSOLE_MANAGER.lock().set_console_title(&args.join(" ")) {
+            fail!("title: there is no console");
+        }
     }
 
-    fn cmd_echo(&self, args: &[&str]) {
-        for (i, arg) in args.iter().enumerate() {
-            if i > 0 {
-                print!(" ");
+    // set [prefix] | set name=[value] | set /a name=expression
+    fn cmd_set(&mut self, args: &[&str]) {
+        let text = args.join(" ");
+        if let Some(assignment) = text.strip_prefix("/a ").or_else(|| text.strip_prefix("/A ")) {
+            let Some((name, expression)) = assignment.split_once('=') else {
+                return usage("set /a name=expression");
+            };
+            let arithmetic = Arithmetic { text: expression.as_bytes(), position: 0, variables: &self.variables };
+            match arithmetic.evaluate() {
+                Ok(value) => {
+                    self.variables.insert(name.trim().to_ascii_uppercase(), value.to_string());
+                }
+                Err(error) => fail!("set: {}", error),
+            }
+            return;
+        }
+        match text.split_once('=') {
+            Some(("", _)) => usage("set [prefix] | set name=[value] | set /a name=expression"),
+            // An empty value unsets it
+            Some((name, "")) => {
+                self.variables.remove(&name.to_ascii_uppercase());
+            }
+            Some((name, value)) => {
+                self.variables.insert(name.to_ascii_uppercase(), String::from(value));
+            }
+            None => {
+                let prefix = text.to_ascii_uppercase();
+                let mut found = false;
+                for (name, value) in self.variables.iter().filter(|(name, _)| name.starts_with(&prefix)) {
+                    println!("{}={}", name, value);
+                    found = true;
+                }
+                if !found && !prefix.is_empty() {
+                    fail!("set: no variable starts with {}", text);
+                }
             }
-            print!("{}", arg);
         }
-        println!();
     }
 
     fn cmd_version(&self) {
@@ -146,29 +4376,587 @@ impl Shell {
     }
 
     fn cmd_memory(&self) {
+        use crate::memory::{demand_paging, frame_allocator, oom, page_cache, swap, zram};
+
+        let (total, free, used) = frame_allocator::memory_stats();
         println!("Memory Information:");
-        println!("  Total Heap: 1024 KB");
-        println!("  Used: ~256 KB (estimated)");
-        println!("  Free: ~768 KB (estimated)");
+        println!("  Total: {} KB", total * 4);
+        println!("  Used:  {} KB", used * 4);
+        println!("  Free:  {} KB", free * 4);
         println!("  Page Size: 4096 bytes");
+        let zram = zram::ZRAM.lock().stats();
+        if zram.capacity_pages > 0 {
+            println!(
+                "  zram: {} of {} KB stored in {} KB, {} pages of one word",
+                zram.stored_pages * 4,
+                zram.capacity_pages * 4,
+                zram.compressed_bytes / 1024,
+                zram.same_pages
+            );
+        }
+        let cache = page_cache::stats();
+        println!("  Page cache: {} KB, {} KB dirty", cache.pages * 4, cache.dirty * 4);
+        let (swap_total, swap_used) = swap::totals();
+        if swap_total > 0 {
+            println!("  Swap: {} of {} KB used", swap_used * 4, swap_total * 4);
+        }
+        println!("  Pages evicted: {}", demand_paging::evicted_pages());
+        if oom::kills() > 0 {
+            println!("  Processes killed for memory: {}", oom::kills());
+        }
     }
 
     fn cmd_processes(&self) {
         use crate::process::executor::EXECUTOR;
         
+        use crate::drivers::block;
+        
         println!("Process List:");
-        println!("  PID | Name            | State");
-        println!("  ----|-----------------|--------");
+        println!("  PID | Name            | State    | I/O    |     Read KiB |  Written KiB | Wait ms");
+        println!("  ----|-----------------|----------|--------|--------------|--------------|--------");
         
-        let executor = EXECUTOR.lock();
-        for (pid, name, state) in executor.list_processes() {
-            println!("  {:3} | {:15} | {}", pid, name, state);
+        let processes = EXECUTOR.lock().list_processes();
+        for (pid, name, state) in processes {
+            let io = block::stats(pid);
+            println!(
+                "  {:3} | {:15} | {:8} | {:6} | {:12} | {:12} | {:7}",
+                pid,
+                name,
+                state,
+                format!("{}", block::priority(pid)),
+                io.read_bytes / 1024,
+                io.write_bytes / 1024,
+                io.wait_ns / 1_000_000
+            );
+        }
+    }
+
+    fn cmd_tasklist(&self, args: &[&str]) {
+        let verbose = match args {
+            [] => false,
+            [flag] if flag.eq_ignore_ascii_case("/v") => true,
+            _ => return usage("tasklist [/v]"),
+        };
+        // Taken before the executor's lock, which the report takes too
+        let pages: BTreeMap<u32, usize> =
+            crate::memory::oom::report().into_iter().map(|score| (score.pid, score.pages)).collect();
+        let mut rows: Vec<(u32, Option<u32>, String, String, String)> = {
+            let executor = EXECUTOR.lock();
+            let states: BTreeMap<u32, String> =
+                executor.list_processes().into_iter().map(|(pid, _, state)| (pid, state)).collect();
+            executor
+                .processes()
+                .map(|pcb| {
+                    let state = states.get(&pcb.pid).cloned().unwrap_or_default();
+                    (pcb.pid, pcb.ppid, pcb.name.clone(), state, pcb.command_line.clone())
+                })
+                .collect()
+        };
+        rows.sort_by_key(|row| row.0);
+
+        println!("{:<25} {:>8} {:>8} {:<9} {:>12}", "Image Name", "PID", "PPID", "Status", "Mem Usage");
+        println!("{} {} {} {} {}", "=".repeat(25), "=".repeat(8), "=".repeat(8), "=".repeat(9), "=".repeat(12));
+        for (pid, ppid, name, state, command_line) in rows {
+            let ppid = ppid.map_or(String::from("-"), |ppid| format!("{}", ppid));
+            let memory = format!("{} K", thousands(pages.get(&pid).copied().unwrap_or(0) as u64 * 4));
+            let name: String = name.chars().take(25).collect();
+            if verbose {
+                println!("{:<25} {:>8} {:>8} {:<9} {:>12} {}", name, pid, ppid, state, memory, command_line);
+            } else {
+                println!("{:<25} {:>8} {:>8} {:<9} {:>12}", name, pid, ppid, state, memory);
+            }
+        }
+    }
+
+    // Anyone may end the programs they started from this shell, and what
+    // those started; administrators may end anything but PID 1
+    fn cmd_taskkill(&self, args: &[&str]) {
+        const USAGE: &str = "taskkill /pid n | /im name [/t] [/f]";
+
+        let mut pids: Vec<u32> = Vec::new();
+        let mut images: Vec<&str> = Vec::new();
+        let mut tree = false;
+        let mut words = args.iter();
+        while let Some(word) = words.next() {
+            match word.to_ascii_lowercase().as_str() {
+                "/pid" => match words.next().and_then(|pid| pid.parse().ok()) {
+                    Some(pid) => pids.push(pid),
+                    None => return usage(USAGE),
+                },
+                "/im" => match words.next() {
+                    Some(image) => images.push(*image),
+                    None => return usage(USAGE),
+                },
+                "/t" => tree = true,
+                // Every process is ended at once; nothing asks it to close
+                "/f" => {}
+                _ => return usage(USAGE),
+            }
+        }
+        if pids.is_empty() && images.is_empty() {
+            return usage(USAGE);
+        }
+
+        // PID, parent and name of every process
+        let processes: Vec<(u32, Option<u32>, String)> =
+            EXECUTOR.lock().processes().map(|pcb| (pcb.pid, pcb.ppid, pcb.name.clone())).collect();
+        let parent = |pid: u32| processes.iter().find(|p| p.0 == pid).and_then(|p| p.1);
+        let mut targets: Vec<u32> = Vec::new();
+        for pid in pids {
+            if processes.iter().any(|p| p.0 == pid) {
+                targets.push(pid);
+            } else {
+                fail!("ERROR: The process \"{}\" not found.", pid);
+            }
+        }
+        for image in images {
+            let before = targets.len();
+            targets.extend(processes.iter().filter(|p| glob_match(image.as_bytes(), p.2.as_bytes())).map(|p| p.0));
+            if targets.len() == before {
+                fail!("ERROR: The process \"{}\" not found.", image);
+            }
+        }
+        let mut seen: Vec<u32> = Vec::new();
+        targets.retain(|pid| !seen.contains(pid) && {
+            seen.push(*pid);
+            true
+        });
+        if tree {
+            // Children before their parents, as they are ended in order
+            let mut index = 0;
+            while index < targets.len() {
+                let children: Vec<u32> = processes
+                    .iter()
+                    .filter(|p| p.1 == Some(targets[index]) && !targets.contains(&p.0))
+                    .map(|p| p.0)
+                    .collect();
+                targets.extend(children);
+                index += 1;
+            }
+            targets.reverse();
+        }
+
+        let admin = accounts::caller_is_admin();
+        let jobs: Vec<u32> = self.jobs.iter().map(|job| job.pid).collect();
+        let started_here = |mut pid: u32| loop {
+            if jobs.contains(&pid) {
+                return true;
+            }
+            match parent(pid) {
+                Some(ppid) if ppid != pid => pid = ppid,
+                _ => return false,
+            }
+        };
+        for pid in targets {
+            let name = processes.iter().find(|p| p.0 == pid).map_or("", |p| p.2.as_str());
+            if pid == 1 {
+                fail!("ERROR: The process \"{}\" with PID 1 could not be terminated: it is the init process.", name);
+            } else if !admin && !started_here(pid) {
+                access_denied(&format!("taskkill: PID {}", pid));
+            } else {
+                end_process(pid, EXIT_FAILURE);
+                println!("SUCCESS: The process \"{}\" with PID {} has been terminated.", name, pid);
+            }
+        }
+    }
+
+    fn cmd_meminfo(&self) {
+        print!("{}", crate::memory::proc_meminfo());
+    }
+
+    fn cmd_cpuinfo(&self) {
+        use crate::smp::{CpuInfo, MAX_CPUS, SMP_MANAGER};
+
+        let info = crate::cpu::get_info();
+        let cache = |bytes: u32| if bytes > 0 { format!("{} KB", bytes / 1024) } else { String::from("-") };
+        println!("Vendor:       {}", crate::cpu::get_vendor().unwrap_or_default());
+        println!("Model name:   {}", crate::cpu::get_model().unwrap_or_default().trim());
+        println!("Family:       {}  Model: {}  Stepping: {}", info.family, info.model, info.stepping);
+        println!("TSC:          {} MHz", crate::time::tsc::frequency() / 1_000_000);
+        println!("Package:      {} cores, {} threads", info.physical_cores, info.logical_cores);
+        println!(
+            "Caches:       L1d {}  L1i {}  L2 {}  L3 {}  line {} bytes",
+            cache(info.l1_data_cache),
+            cache(info.l1_inst_cache),
+            cache(info.l2_cache),
+            cache(info.l3_cache),
+            info.cache_line_size
+        );
+        println!("Flags:        {}", crate::cpu::flags().join(" "));
+
+        let cpus: Vec<CpuInfo> = {
+            let smp = SMP_MANAGER.lock();
+            (0..MAX_CPUS as u32).filter_map(|cpu| smp.get_cpu(cpu).cloned()).collect()
+        };
+        println!();
+        println!("{:>4} {:>5} {:>8} {:>5} {:>7} {:>5} {:<8}", "CPU", "APIC", "PACKAGE", "CORE", "THREAD", "NODE", "STATE");
+        for cpu in cpus {
+            println!(
+                "{:>4} {:>5} {:>8} {:>5} {:>7} {:>5} {:<8}{}",
+                cpu.cpu_id,
+                cpu.apic_id,
+                cpu.package_id,
+                cpu.core_id,
+                cpu.thread_id,
+                cpu.numa_node,
+                format!("{:?}", cpu.state),
+                if cpu.is_bsp { " boot" } else { "" }
+            );
+        }
+    }
+
+    fn cmd_lspci(&self) {
+        use crate::driver::{bus::pci_name, Ident, DEVICES};
+        use crate::pcie::{PciLocation, PCIE_CONTROLLER};
+
+        let functions = PCIE_CONTROLLER.lock().devices().to_vec();
+        if functions.is_empty() {
+            println!("No PCI functions found");
+            return;
+        }
+        let devices = DEVICES.lock();
+        let driver = |location: PciLocation| {
+            devices
+                .iter()
+                .find(|device| matches!(device.ident, Ident::Pci { location: found, .. } if found == location))
+                .and_then(|device| device.driver)
+                .unwrap_or("-")
+        };
+        println!("{:<13} {:<31} {:<9} {:>3}  {}", "SLOT", "CLASS", "ID", "REV", "DRIVER");
+        for function in &functions {
+            println!(
+                "{:<13} {:<31} {:04x}:{:04x} {:>3}  {}",
+                pci_name(&function.location),
+                function.class_name(),
+                function.vendor_id,
+                function.device_id,
+                format!("{:02x}", function.revision),
+                driver(function.location)
+            );
+        }
+    }
+
+    fn cmd_lsusb(&self) {
+        use crate::driver::{Ident, DEVICES};
+        use crate::usb::device::{class_name, speed_name};
+
+        let attached = crate::usb::USB_MANAGER.lock().get_devices().to_vec();
+        if attached.is_empty() {
+            println!("No USB devices found");
+            return;
+        }
+        let devices = DEVICES.lock();
+        let driver = |address: u8| {
+            devices
+                .iter()
+                .find(|device| matches!(device.ident, Ident::Usb { address: found, .. } if found == address))
+                .and_then(|device| device.driver)
+                .unwrap_or("-")
+        };
+        println!("{:>4} {:>8} {:<9} {:<15} {:<14} {:<10} {}", "ADDR", "HUB:PORT", "ID", "SPEED", "CLASS", "DRIVER", "PRODUCT");
+        for device in &attached {
+            let hub = device.parent_hub.map_or(String::from("root"), |hub| format!("{}", hub));
+            let product = format!("{} {}", device.manufacturer, device.product);
+            // Copied out, as the descriptor is packed
+            let (vendor, id) = (device.device_desc.vendor_id, device.device_desc.product_id);
+            println!(
+                "{:>4} {:>8} {:04x}:{:04x} {:<15} {:<14} {:<10} {}",
+                device.address,
+                format!("{}:{}", hub, device.port),
+                vendor,
+                id,
+                speed_name(device.speed),
+                class_name(device.class),
+                driver(device.address),
+                product.trim()
+            );
+        }
+    }
+
+    fn cmd_ethtool(&self, args: &[&str]) {
+        use crate::net::buffer::Offloads;
+        use crate::net::interface;
+
+        const USAGE: &str = "ethtool [iface [coalesce usecs | rss n]]";
+        if args.len() > 1 && !accounts::caller_is_admin() {
+            return access_denied("ethtool");
+        }
+        match args {
+            [] => {
+                let interfaces = interface::interfaces();
+                if interfaces.is_empty() {
+                    return println!("No network interfaces");
+                }
+                for info in interfaces {
+                    let queues = interface::queue_info(&info.name).map_or(0, |queues| queues.queues.len());
+                    let link = if info.link_up { "up" } else { "down" };
+                    println!("{:<8} {} link {:<4} {} queue(s)", info.name, info.mac, link, queues);
+                }
+            }
+            [name] => {
+                let Some(info) = interface::queue_info(name) else {
+                    return fail!("ethtool: {}: no such interface", name);
+                };
+                let offloads: Vec<&str> = [
+                    (Offloads::SCATTER_GATHER, "scatter-gather"),
+                    (Offloads::CHECKSUM, "checksum"),
+                    (Offloads::TSO, "tso"),
+                ]
+                .iter()
+                .filter(|(offload, _)| info.offloads.contains(*offload))
+                .map(|&(_, label)| label)
+                .collect();
+                let offloads = if offloads.is_empty() { String::from("none") } else { offloads.join(", ") };
+                println!("Offloads:   {}", offloads);
+                match &info.rss {
+                    Some(rss) => println!("RSS:        {} of {} queues", rss.queues(), info.queues.len()),
+                    None => println!("RSS:        off"),
+                }
+                println!("Moderation: {} us", info.moderation_us);
+                println!(
+                    "{:>5} {:>3} {:>10} {:>9} {:>10} {:>9} {:>6} {:>10} {:>10}",
+                    "QUEUE", "CPU", "RX", "RX BYTES", "TX", "TX BYTES", "ERRORS", "POLLS", "BUSY"
+                );
+                for (index, queue) in info.queues.iter().enumerate() {
+                    println!(
+                        "{:>5} {:>3} {:>10} {:>9} {:>10} {:>9} {:>6} {:>10} {:>10}",
+                        index,
+                        queue.cpu,
+                        queue.rx_packets,
+                        human_size(queue.rx_bytes),
+                        queue.tx_packets,
+                        human_size(queue.tx_bytes),
+                        queue.tx_errors,
+                        queue.polls,
+                        queue.busy_polls
+                    );
+                }
+            }
+            [name, "coalesce", usecs] => {
+                let Ok(usecs) = usecs.parse::<u32>() else {
+                    return usage("ethtool iface coalesce usecs");
+                };
+                if let Err(error) = interface::set_moderation(name, usecs) {
+                    fail!("ethtool: {}: {}", name, error);
+                }
+            }
+            [name, "rss", queues] => {
+                let Ok(queues) = queues.parse::<usize>() else {
+                    return usage("ethtool iface rss n");
+                };
+                if let Err(error) = interface::set_rss(name, queues) {
+                    fail!("ethtool: {}: {}", name, error);
+                }
+            }
+            _ => usage(USAGE),
+        }
+    }
+
+    fn cmd_df(&self, args: &[&str]) {
+        let human = match args {
+            [] => false,
+            ["-h"] => true,
+            _ => return usage("df [-h]"),
+        };
+        let mounts = VFS.lock().mounts();
+        let size = |bytes: u64| if human { human_size(bytes) } else { format!("{}", bytes / 1024) };
+        let heading = if human { "Size" } else { "1K-blocks" };
+        println!("{:<10} {:>10} {:>10} {:>10} {:>5} {}", "Filesystem", heading, "Used", "Available", "Use%", "Mounted on");
+        for (mount_point, fs_type, stats) in mounts {
+            let stats = stats.unwrap_or_default();
+            let total = stats.blocks * stats.block_size;
+            let free = stats.free_blocks * stats.block_size;
+            let used = total - free;
+            // Rounded up, as df does, so only an empty one shows 0%
+            let percent = match total {
+                0 => String::from("-"),
+                total => format!("{}%", (used * 100).div_ceil(total)),
+            };
+            println!("{:<10} {:>10} {:>10} {:>10} {:>5} {}", fs_type, size(total), size(used), size(free), percent, mount_point);
+        }
+    }
+
+    fn cmd_chkdsk(&self, args: &[&str]) {
+        use crate::fs::chkdsk;
+
+        let repair = args.iter().any(|arg| arg.eq_ignore_ascii_case("/f"));
+        let disks: Vec<&str> = args.iter().copied().filter(|arg| !arg.eq_ignore_ascii_case("/f")).collect();
+        let disk = match disks[..] {
+            [] => chkdsk::root_disk(),
+            [name] => match name.strip_prefix("disk").and_then(|index| index.parse().ok()) {
+                Some(index) => index,
+                None => return usage("chkdsk [diskN] [/f]"),
+            },
+            _ => return usage("chkdsk [diskN] [/f]"),
+        };
+        if !accounts::caller_is_admin() {
+            return access_denied("chkdsk");
+        }
+        if repair && chkdsk::mounted(disk) {
+            fail!("chkdsk: disk{} is mounted; boot with chkdsk=force to repair it", disk);
+            return;
+        }
+
+        let report = match chkdsk::check(disk, repair) {
+            Ok(report) => report,
+            Err(e) => {
+                fail!("chkdsk: disk{}: {}", disk, e);
+                return;
+            }
+        };
+        println!("The type of the file system is {}.", report.fs_type);
+        if report.dirty {
+            println!("The volume is marked dirty.");
+        }
+        for problem in &report.problems {
+            println!("  {}{}", problem.text, if problem.fixed { " (fixed)" } else { "" });
+        }
+        match (report.problems.len(), report.unfixed()) {
+            (0, _) => println!("No problems were found."),
+            (found, 0) => println!("{} problems were found and fixed.", found),
+            (found, left) if repair => println!("{} problems were found; {} could not be fixed.", found, left),
+            (found, _) => println!("{} problems were found. Run chkdsk /f to fix them.", found),
+        }
+        let kb = |clusters: u64| thousands(clusters * report.cluster_size / 1024);
+        println!("{:>16} KB total disk space", kb(report.clusters));
+        println!("{:>16} KB in {} files", kb(report.clusters - report.free_clusters), thousands(report.files));
+        println!("{:>16} directories", thousands(report.directories));
+        println!("{:>16} KB in bad sectors", kb(report.bad_clusters));
+        println!("{:>16} KB available on disk", kb(report.free_clusters));
+        println!("{:>16} bytes in each allocation unit", thousands(report.cluster_size));
+        if report.unfixed() > 0 {
+            EXIT_CODE.store(EXIT_FAILURE, Ordering::Relaxed);
         }
     }
 
     fn cmd_uptime(&self) {
-        // This would normally calculate from timer ticks
-        println!("System uptime: 00:00:42");
+        let secs = crate::time::monotonic_ns() / crate::time::NS_PER_SEC;
+        println!("System uptime: {:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    }
+
+    fn cmd_date(&self, args: &[&str]) {
+        use crate::time::{self, zone};
+
+        match args {
+            [] => {
+                let utc = time::now_utc();
+                let zone = zone::current();
+                println!("{} {}", time::now_local(), zone.abbreviation_at(utc.to_unix()));
+                println!("{} UTC", utc);
+            }
+            ["/sync", url] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("date");
+                    return;
+                }
+                match crate::net::http::Url::parse(url) {
+                    Ok(url) => {
+                        let request = crate::net::http::client::Request::head(url);
+                        let id = crate::net::http::spawn(request, crate::net::http::Action::SetClock);
+                        println!("Setting the clock from the server's Date (transfer {})", id);
+                    }
+                    Err(error) => fail!("date: {}", error),
+                }
+            }
+            [date, clock] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("date");
+                    return;
+                }
+                match Self::parse_date_time(date, clock).map(|local| time::set_local(&local)) {
+                    Some(Ok(())) => println!("{}", time::now_local()),
+                    Some(Err(error)) => fail!("date: {}", error),
+                    None => usage("date [YYYY-MM-DD HH:MM[:SS] | /sync url]"),
+                }
+            }
+            _ => usage("date [YYYY-MM-DD HH:MM[:SS] | /sync url]"),
+        }
+    }
+
+    // YYYY-MM-DD and HH:MM[:SS]
+    fn parse_date_time(date: &str, clock: &str) -> Option<crate::time::DateTime> {
+        let numbers = |text: &str, separator: char| -> Option<Vec<u16>> {
+            text.split(separator).map(|part| part.parse().ok()).collect()
+        };
+        let (year, month, day) = match numbers(date, '-')?[..] {
+            [year, month, day] => (year, month, day),
+            _ => return None,
+        };
+        let (hour, minute, second) = match numbers(clock, ':')?[..] {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return None,
+        };
+        let field = |value: u16| u8::try_from(value).ok();
+        Some(crate::time::DateTime {
+            year,
+            month: field(month)?,
+            day: field(day)?,
+            hour: field(hour)?,
+            minute: field(minute)?,
+            second: field(second)?,
+            millisecond: 0,
+        })
+    }
+
+    fn cmd_tz(&self, args: &[&str]) {
+        use crate::time::zone::{self, TimeZone};
+
+        match args {
+            [] => {
+                let current = zone::current();
+                let now = crate::time::now_utc().to_unix();
+                let offset = current.offset_at(now);
+                println!(
+                    "Time zone: {} (UTC{}{:02}:{:02}), {}",
+                    if current.spec.is_empty() { "UTC" } else { current.spec.as_str() },
+                    if offset < 0 { '-' } else { '+' },
+                    offset.abs() / 3600,
+                    offset.abs() / 60 % 60,
+                    if current.is_dst(now) { "daylight saving" } else { "standard time" }
+                );
+                println!("Zones: {}", zone::names().join(" "));
+            }
+            [name] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("tz");
+                    return;
+                }
+                match TimeZone::lookup(name) {
+                    Some(zone) => {
+                        zone::set(zone);
+                        println!("{}", crate::time::now_local());
+                    }
+                    None => fail!("tz: unknown zone or bad TZ rule '{}'", name),
+                }
+            }
+            _ => usage("tz [zone|TZ]"),
+        }
+    }
+
+    fn cmd_hwclock(&self, args: &[&str]) {
+        use crate::time::{self, rtc};
+
+        match args {
+            [] => {}
+            [mode @ ("local" | "utc")] => {
+                if !accounts::caller_is_admin() {
+                    access_denied("hwclock");
+                    return;
+                }
+                time::set_rtc_local(*mode == "local");
+            }
+            _ => {
+                usage("hwclock [local|utc]");
+                return;
+            }
+        }
+        match rtc::read() {
+            Some(reading) => {
+                println!("RTC: {} {}", reading, if time::rtc_local() { "local" } else { "UTC" })
+            }
+            None => println!("RTC: unreadable"),
+        }
     }
 
     fn cmd_test(&self) {
@@ -176,22 +4964,18 @@ impl Shell {
         run_all_tests();
     }
 
-    fn cmd_shutdown(&self) {
-        println!("Shutting down...");
-        serial_println!("System shutdown requested");
-        // In real implementation, would properly shutdown
-        loop {
-            x86_64::instructions::hlt();
-        }
+    fn cmd_shutdown(&self, args: &[&str]) {
+        let action = match args {
+            [] => ShutdownAction::PowerOff,
+            [flag] if flag.eq_ignore_ascii_case("/s") => ShutdownAction::PowerOff,
+            [flag] if flag.eq_ignore_ascii_case("/r") => ShutdownAction::Reboot,
+            _ => return usage("shutdown [/s | /r]"),
+        };
+        shut_down("shutdown", action);
     }
 
     fn cmd_reboot(&self) {
-        println!("Rebooting...");
-        serial_println!("System reboot requested");
-        // Trigger a triple fault to reboot (simple method)
-        unsafe {
-            core::ptr::null_mut::<u8>().write(0);
-        }
+        shut_down("reboot", ShutdownAction::Reboot);
     }
     
     fn cmd_ls(&self, args: &[&str]) {
@@ -199,7 +4983,7 @@ impl Shell {
         
         let path = if args.is_empty() { "/" } else { args[0] };
         
-        let vfs = VFS.lock();
+        let mut vfs = VFS.lock();
         match vfs.list_directory(path) {
             Ok(files) => {
                 println!("Directory listing of {}:", path);
@@ -216,46 +5000,264 @@ impl Shell {
                 }
             },
             Err(_) => {
-                println!("Error: Cannot list directory '{}'", path);
+                fail!("Error: Cannot list directory '{}'", path);
             }
         }
     }
     
     fn cmd_cat(&self, args: &[&str]) {
-        use crate::fs::vfs::VFS;
-        
-        if args.is_empty() {
-            println!("Usage: cat <filename>");
-            return;
+        let text = match args {
+            [] => match &self.stdin {
+                Some(text) => text.clone(),
+                None => return usage("cat <filename>"),
+            },
+            [path, ..] => match read_text(path) {
+                Ok(text) => text,
+                Err(_) => return fail!("Error: Cannot read file '{}'", path),
+            },
+        };
+        for c in text.chars().filter(char::is_ascii) {
+            print!("{}", c);
         }
-        
-        let path = args[0];
-        let vfs = VFS.lock();
-        
-        match vfs.read_file(path) {
-            Ok(data) => {
-                // Convert bytes to string and print
-                let last_byte = data.last().copied();
-                for byte in data {
-                    if byte.is_ascii() {
-                        print!("{}", byte as char);
+        if !text.is_empty() && !text.ends_with('\n') {
+            println!(); // Add newline if file doesn't end with one
+        }
+    }
+
+    // A filter's input: the file named, or else what was piped or
+    // redirected in
+    fn filter_input(&self, path: Option<&&str>) -> Option<String> {
+        match path {
+            Some(path) => match read_text(path) {
+                Ok(text) => Some(text),
+                Err(_) => {
+                    fail!("Error: Cannot read file '{}'", path);
+                    None
+                }
+            },
+            None => Some(self.stdin.clone().unwrap_or_default()),
+        }
+    }
+
+    // Lines containing the text; fails if there are none, as findstr does
+    fn cmd_find(&self, args: &[&str]) {
+        let [pattern, path @ ..] = args else {
+            return usage("find text [file]");
+        };
+        if path.len() > 1 {
+            return usage("find text [file]");
+        }
+        let Some(text) = self.filter_input(path.first()) else { return };
+        let mut found = false;
+        for line in text.lines().filter(|line| line.contains(pattern)) {
+            println!("{}", line);
+            found = true;
+        }
+        if !found {
+            EXIT_CODE.store(EXIT_FAILURE, Ordering::Relaxed);
+        }
+    }
+
+    fn cmd_sort(&self, args: &[&str]) {
+        let (reverse, path) = match args {
+            ["/r" | "-r", rest @ ..] => (true, rest),
+            rest => (false, rest),
+        };
+        if path.len() > 1 {
+            return usage("sort [/r] [file]");
+        }
+        let Some(text) = self.filter_input(path.first()) else { return };
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines.sort_unstable();
+        if reverse {
+            lines.reverse();
+        }
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+
+    // Copy a file or what was piped in to the clipboard; with neither,
+    // print the clipboard's text
+    fn cmd_clip(&self, args: &[&str]) {
+        use crate::clipboard;
+
+        match args {
+            ["/l" | "-l"] => {
+                println!("Sequence {}", clipboard::sequence());
+                for format in clipboard::formats() {
+                    let name = clipboard::format_name(format).unwrap_or_else(|| format!("{:#x}", format));
+                    let size = clipboard::get(format).map_or(0, |bytes| bytes.len());
+                    println!("  {:<20} {:>8} bytes", name, size);
+                }
+            }
+            [] if self.stdin.is_none() => match clipboard::text() {
+                Some(text) => {
+                    print!("{}", text);
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        println!();
                     }
                 }
-                if let Some(last) = last_byte {
-                    if last != b'\n' {
-                        println!();  // Add newline if file doesn't end with one
+                None => fail!("clip: the clipboard holds no text"),
+            },
+            [] | [_] => {
+                let Some(text) = self.filter_input(args.first()) else { return };
+                clipboard::set_text(&text);
+            }
+            _ => usage("clip [/l] [file]"),
+        }
+    }
+    
+    // The resources of a program or DLL, or with /v its version
+    fn cmd_res(&self, args: &[&str]) {
+        use crate::win32::resource::{self, PeImage};
+
+        let (path, version) = match args {
+            [path] => (path, false),
+            [path, "/v" | "-v"] => (path, true),
+            _ => return usage("res file [/v]"),
+        };
+        let data = match VFS.lock().read_file(path) {
+            Ok(data) => data,
+            Err(_) => return fail!("res: cannot read '{}'", path),
+        };
+        let Some(image) = PeImage::file(&data) else {
+            return fail!("res: '{}' is not an image with resources", path);
+        };
+        if !version {
+            for (kind, name, language, size) in image.list() {
+                let (kind, name) = (resource::type_name(&kind), name.to_string());
+                println!("{:<14} {:<16} {:04x} {:>8} bytes", kind, name, language, size);
+            }
+            return;
+        }
+        let Some(info) = image.version() else {
+            return fail!("res: '{}' has no version information", path);
+        };
+        let dotted = |v: [u16; 4]| format!("{}.{}.{}.{}", v[0], v[1], v[2], v[3]);
+        println!("{:<18} {}", "FileVersion", dotted(info.file_version));
+        println!("{:<18} {}", "ProductVersion", dotted(info.product_version));
+        for (key, value) in info.strings {
+            println!("{:<18} {}", key, value);
+        }
+    }
+    
+    fn cmd_edit(&mut self, args: &[&str]) {
+        let [path] = args else {
+            return usage("edit file");
+        };
+        let app = edit::Editor::open(path).map(|editor| Box::new(editor) as Box<dyn App>);
+        self.open_screen("edit", app);
+    }
+
+    fn cmd_hexedit(&mut self, args: &[&str]) {
+        let [path] = args else {
+            return usage("hexedit file");
+        };
+        let app = hexedit::HexEditor::open(path).map(|editor| Box::new(editor) as Box<dyn App>);
+        self.open_screen("hexedit", app);
+    }
+
+    // Give a full-screen command the display and the keyboard. It has no
+    // output to pipe or redirect, nor any use for input.
+    fn open_screen(&mut self, command: &str, app: Result<Box<dyn App>, String>) {
+        if self.stdin.is_some() || CAPTURE.lock().is_some() {
+            return fail!("{}: cannot be piped or redirected", command);
+        }
+        match app {
+            Ok(app) => self.screen = Some(Screen::open(app)),
+            Err(message) => fail!("{}: {}", command, message),
+        }
+    }
+
+    // Bytes in hexdump -C's format, of a file or the input; -s skips to an
+    // offset and -n stops after a length
+    fn cmd_hexdump(&self, args: &[&str]) {
+        const USAGE: &str = "hexdump [-s offset] [-n length] [file]";
+        let (mut skip, mut length, mut path) = (0, None, None);
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "-s" | "-n" => {
+                    let Some(value) = args.next().and_then(|value| hexedit::parse_offset(value)) else {
+                        return usage(USAGE);
+                    };
+                    if arg == "-s" {
+                        skip = value;
+                    } else {
+                        length = Some(value);
                     }
                 }
+                _ if path.is_none() => path = Some(arg),
+                _ => return usage(USAGE),
+            }
+        }
+        let data = match path {
+            Some(path) => match VFS.lock().read_file(path) {
+                Ok(data) => data,
+                Err(_) => return fail!("Error: Cannot read file '{}'", path),
             },
+            None => self.stdin.clone().unwrap_or_default().into_bytes(),
+        };
+
+        let end = length.map_or(data.len(), |length| skip.saturating_add(length)).min(data.len());
+        let start = skip.min(end);
+        // Runs of identical rows show as one, then a *
+        let mut previous: Option<&[u8]> = None;
+        let mut repeated = false;
+        for offset in (start..end).step_by(hexedit::BYTES_PER_ROW) {
+            let bytes = &data[offset..(offset + hexedit::BYTES_PER_ROW).min(end)];
+            if previous == Some(bytes) && bytes.len() == hexedit::BYTES_PER_ROW {
+                if !repeated {
+                    println!("*");
+                    repeated = true;
+                }
+                continue;
+            }
+            previous = Some(bytes);
+            repeated = false;
+            println!("{}", hexedit::row(offset, bytes));
+        }
+        println!("{:08x}", end);
+    }
+
+    fn cmd_sandbox(&self, args: &[&str]) {
+        use crate::fs::vfs::VFS;
+        use crate::security::sandbox::{spawn_sandboxed, SandboxProfile};
+
+        if args.len() < 2 {
+            usage("sandbox <profile> <name>");
+            return;
+        }
+
+        let text = match VFS.lock().read_file(args[0]) {
+            Ok(data) => String::from_utf8_lossy(&data).into_owned(),
             Err(_) => {
-                println!("Error: Cannot read file '{}'", path);
+                fail!("Error: Cannot read profile '{}'", args[0]);
+                return;
+            }
+        };
+        let profile = match SandboxProfile::parse(&text) {
+            Ok(profile) => profile,
+            Err(error) => {
+                fail!("sandbox: {:?}", error);
+                return;
             }
+        };
+
+        let parent = crate::process::PROCESS_MANAGER
+            .lock()
+            .current_process
+            .map_or(1, |pid| pid.0 as u64);
+        match spawn_sandboxed(parent, String::from(args[1]), &profile) {
+            Ok(pid) => println!("Process {} started in sandbox '{}'", pid, profile.name),
+            Err(error) => fail!("sandbox: {:?}", error),
         }
     }
-    
+
     fn cmd_execute(&self, args: &[&str]) {
         if args.is_empty() {
-            println!("Usage: exec <filename.exe>");
+            usage("exec <filename.exe>");
             return;
         }
         
@@ -428,15 +5430,51 @@ pub static SHELL: Mutex<Option<Shell>> = Mutex::new(None);
 
 pub fn init() {
     // Initialize shell during boot
-    let shell = Shell::new();
+    let mut shell = Shell::new();
     shell.print_welcome();
-    shell.print_prompt();
+    // Provisioning, run before anyone logs on
+    let autoexec = AUTOEXEC.get();
+    if let Ok(text) = read_text(autoexec) {
+        crate::pr_info!("shell: running {}", autoexec);
+        let lines = text.lines().map(String::from).collect();
+        shell.batches.push(Batch { lines, next: 0, args: Some(alloc::vec![String::from(autoexec)]) });
+    } else {
+        shell.print_prompt();
+    }
     *SHELL.lock() = Some(shell);
     crate::serial_println!("Shell initialized and ready for commands");
 }
 
+// Run the shell's process under the given logon token
+fn attach_token(token: Handle) {
+    let mut manager = crate::process::PROCESS_MANAGER.lock();
+    if let Some(pid) = manager.current_process {
+        manager.set_token(pid, token);
+    }
+}
+
 pub fn handle_keyboard_input(character: char) {
     if let Some(ref mut shell) = *SHELL.lock() {
         shell.handle_key(character);
     }
+}
+
+/// Notice programs the shell started that have ended, and run more of any
+/// batch file. Called from the main loop.
+pub fn poll() {
+    if let Some(ref mut shell) = *SHELL.lock() {
+        // A full-screen command keeps the display until it exits
+        if shell.screen.is_some() {
+            return;
+        }
+        if !shell.jobs.is_empty() {
+            shell.reap();
+        }
+        if !shell.batches.is_empty() && shell.foreground.is_none() {
+            shell.run_batches();
+            if !shell.busy() {
+                shell.print_prompt();
+            }
+        }
+    }
 }
\ No newline at end of file
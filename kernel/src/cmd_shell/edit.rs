@@ -0,0 +1,414 @@
+// `edit`: a full-screen text editor, after nano
+//
+// The whole file is held as lines of characters. Tabs show as spaces to the
+// next multiple of eight, and a line wider than the screen scrolls the view
+// sideways with the cursor. A file is written back with the line endings it
+// was read with, and always ends with one.
+//
+// Lines cut go onto the system clipboard too, and ^U pastes what is there
+// if something else has been copied since.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::editor::{ctrl, Key};
+use super::screen::{self, App, Prompt, Reply, EXIT, INTERRUPT, ROWS, SAVE, STATUS_ROW, WIDTH, WRITE_OUT};
+use crate::fs::vfs::VFS;
+use crate::fs::FileSystemError;
+
+const TAB_WIDTH: usize = 8;
+
+const CUT: char = ctrl('k');
+const PASTE: char = ctrl('u');
+const WHERE_IS: char = ctrl('w');
+
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("^O", "Write Out"),
+    ("^X", "Exit"),
+    ("^W", "Where Is"),
+    ("^S", "Save"),
+    ("^K", "Cut Line"),
+    ("^U", "Paste"),
+    ("^C", "Cur Pos"),
+    ("^Y", "Prev Page"),
+    ("^A", "Home"),
+    ("^V", "Next Page"),
+];
+
+enum Question {
+    WriteOut,
+    WhereIs,
+}
+
+enum Mode {
+    Editing,
+    Asking(Question, Prompt),
+    // Save modified buffer? Y, N, or cancel
+    ConfirmExit,
+}
+
+pub struct Editor {
+    path: String,
+    lines: Vec<Vec<char>>,
+    crlf: bool,
+    modified: bool,
+    row: usize,
+    col: usize,
+    // The first line and the first column shown
+    top: usize,
+    left: usize,
+    // Lines cut with ^K, the last run of them
+    cut: Vec<Vec<char>>,
+    cutting: bool,
+    // The clipboard's sequence number when `cut` was last in step with it
+    cut_sequence: u32,
+    last_search: String,
+    mode: Mode,
+    message: String,
+}
+
+// Where character `col` of a line is shown, with tabs expanded
+fn display_column(line: &[char], col: usize) -> usize {
+    line[..col.min(line.len())]
+        .iter()
+        .fold(0, |column, &c| if c == '\t' { (column / TAB_WIDTH + 1) * TAB_WIDTH } else { column + 1 })
+}
+
+impl Editor {
+    /// Open `path`, or start a new file there. Fails for a file that is
+    /// not text.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let data = VFS.lock().read_file(path);
+        let (lines, crlf, message) = match data {
+            Ok(data) => {
+                let text = String::from_utf8(data)
+                    .ok()
+                    .filter(|text| !text.contains('\0'))
+                    .ok_or_else(|| format!("{} is not a text file; use hexedit", path))?;
+                let crlf = text.contains("\r\n");
+                let body = text.strip_suffix('\n').unwrap_or(&text);
+                let lines: Vec<Vec<char>> = body
+                    .split('\n')
+                    .map(|line| if crlf { line.strip_suffix('\r').unwrap_or(line) } else { line })
+                    .map(|line| line.chars().collect())
+                    .collect();
+                let message = format!("Read {} lines", lines.len());
+                (lines, crlf, message)
+            }
+            Err(FileSystemError::NotFound) => (alloc::vec![Vec::new()], false, String::from("New File")),
+            Err(FileSystemError::PermissionDenied) => return Err(format!("{}: access denied", path)),
+            Err(error) => return Err(format!("Cannot read {}: {:?}", path, error)),
+        };
+        Ok(Editor {
+            path: String::from(path),
+            lines,
+            crlf,
+            modified: false,
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            cut: Vec::new(),
+            cutting: false,
+            cut_sequence: crate::clipboard::sequence(),
+            last_search: String::new(),
+            mode: Mode::Editing,
+            message,
+        })
+    }
+
+    fn line(&self) -> &[char] {
+        &self.lines[self.row]
+    }
+
+    fn save(&mut self, path: &str) -> bool {
+        let ending = if self.crlf { "\r\n" } else { "\n" };
+        let mut text = String::new();
+        for line in &self.lines {
+            text.extend(line.iter());
+            text += ending;
+        }
+        match VFS.lock().write_file(path, text.as_bytes()) {
+            Ok(()) => {
+                self.path = String::from(path);
+                self.modified = false;
+                self.message = format!("Wrote {} lines", self.lines.len());
+                true
+            }
+            Err(error) => {
+                self.message = format!("Error writing {}: {:?}", path, error);
+                false
+            }
+        }
+    }
+
+    // Find the text after the cursor: on the rest of its line, the lines
+    // below, then from the top round to the cursor again
+    fn search(&mut self, text: &str) {
+        let needle: Vec<char> = text.chars().collect();
+        let count = self.lines.len();
+        for step in 0..=count {
+            let row = (self.row + step) % count;
+            let line = &self.lines[row];
+            let (from, to) = match step {
+                0 => (self.col + 1, line.len()),
+                step if step == count => (0, self.col),
+                _ => (0, line.len()),
+            };
+            if let Some(col) = (from..=to).find(|&start| line[start..].starts_with(&needle)) {
+                self.message = match (step, col == self.col) {
+                    (step, true) if step == count => String::from("This is the only occurrence"),
+                    _ if self.row + step >= count => String::from("Search Wrapped"),
+                    _ => String::new(),
+                };
+                self.row = row;
+                self.col = col;
+                return;
+            }
+        }
+        self.message = format!("\"{}\" not found", text);
+    }
+
+    fn insert(&mut self, c: char) {
+        let (row, col) = (self.row, self.col);
+        self.lines[row].insert(col, c);
+        self.col += 1;
+        self.modified = true;
+    }
+
+    fn newline(&mut self) {
+        let rest = self.lines[self.row].split_off(self.col);
+        self.lines.insert(self.row + 1, rest);
+        self.row += 1;
+        self.col = 0;
+        self.modified = true;
+    }
+
+    fn backspace(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+            let (row, col) = (self.row, self.col);
+            self.lines[row].remove(col);
+            self.modified = true;
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.col = self.line().len();
+            self.lines[self.row].extend(line);
+            self.modified = true;
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.line().len() {
+            let (row, col) = (self.row, self.col);
+            self.lines[row].remove(col);
+            self.modified = true;
+        } else if self.row + 1 < self.lines.len() {
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend(line);
+            self.modified = true;
+        }
+    }
+
+    // ^K: cut the line, adding it to the lines cut just before
+    fn cut_line(&mut self) {
+        if !self.cutting {
+            self.cut.clear();
+        }
+        let line = if self.lines.len() > 1 {
+            self.lines.remove(self.row)
+        } else {
+            core::mem::take(&mut self.lines[0])
+        };
+        self.cut.push(line);
+        let text: String = self.cut.iter().flat_map(|line| line.iter().copied().chain(['\n'])).collect();
+        crate::clipboard::set_text(&text);
+        self.cut_sequence = crate::clipboard::sequence();
+        self.row = self.row.min(self.lines.len() - 1);
+        self.col = 0;
+        self.modified = true;
+    }
+
+    // ^U: put the cut lines back above the cursor's, or the clipboard's
+    // text if it has changed since
+    fn paste(&mut self) {
+        let sequence = crate::clipboard::sequence();
+        if sequence != self.cut_sequence {
+            if let Some(text) = crate::clipboard::text() {
+                self.cut = text.lines().map(|line| line.chars().collect()).collect();
+            }
+            self.cut_sequence = sequence;
+        }
+        for line in self.cut.iter().rev() {
+            self.lines.insert(self.row, line.clone());
+        }
+        self.row += self.cut.len();
+        self.col = 0;
+        self.modified |= !self.cut.is_empty();
+    }
+
+    fn exit(&mut self) -> bool {
+        if self.modified {
+            self.mode = Mode::ConfirmExit;
+            return true;
+        }
+        false
+    }
+
+    fn answer(&mut self, question: Question, answer: String) {
+        match question {
+            Question::WriteOut if answer.is_empty() => self.message = String::from("Cancelled"),
+            Question::WriteOut => {
+                self.save(&answer);
+            }
+            Question::WhereIs => {
+                let text = if answer.is_empty() { self.last_search.clone() } else { answer };
+                if !text.is_empty() {
+                    self.search(&text);
+                    self.last_search = text;
+                }
+            }
+        }
+    }
+
+    fn edit(&mut self, key: char) -> bool {
+        self.cutting = self.cutting && key == CUT;
+        match key {
+            EXIT => return self.exit(),
+            WRITE_OUT => self.mode = Mode::Asking(Question::WriteOut, Prompt::new("File Name to Write", self.path.clone())),
+            SAVE => {
+                let path = self.path.clone();
+                self.save(&path);
+            }
+            WHERE_IS => {
+                let question = if self.last_search.is_empty() { "Search" } else { "Search [last]" };
+                self.mode = Mode::Asking(Question::WhereIs, Prompt::new(question, String::new()));
+            }
+            INTERRUPT => {
+                self.message = format!(
+                    "line {}/{}, col {}/{}",
+                    self.row + 1,
+                    self.lines.len(),
+                    self.col + 1,
+                    self.line().len() + 1
+                )
+            }
+            CUT => {
+                self.cut_line();
+                self.cutting = true;
+            }
+            PASTE => self.paste(),
+            '\n' => self.newline(),
+            '\x08' | '\x7f' => self.backspace(),
+            Key::DELETE => self.delete(),
+            Key::LEFT if self.col > 0 => self.col -= 1,
+            Key::LEFT if self.row > 0 => {
+                self.row -= 1;
+                self.col = self.line().len();
+            }
+            Key::RIGHT if self.col < self.line().len() => self.col += 1,
+            Key::RIGHT if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.col = 0;
+            }
+            Key::UP => self.row = self.row.saturating_sub(1),
+            Key::DOWN => self.row = (self.row + 1).min(self.lines.len() - 1),
+            Key::PAGE_UP => {
+                self.row = self.row.saturating_sub(ROWS);
+                self.top = self.top.saturating_sub(ROWS);
+            }
+            Key::PAGE_DOWN => {
+                self.row = (self.row + ROWS).min(self.lines.len() - 1);
+                self.top += ROWS;
+            }
+            Key::HOME => self.col = 0,
+            Key::END => self.col = self.line().len(),
+            '\t' => self.insert('\t'),
+            key if key.is_ascii() && !key.is_control() => self.insert(key),
+            _ => {}
+        }
+        true
+    }
+
+    // Keep the cursor within its line, and in view
+    fn scroll(&mut self) {
+        self.col = self.col.min(self.line().len());
+        self.top = self.top.min(self.lines.len().saturating_sub(1)).min(self.row);
+        if self.row >= self.top + ROWS {
+            self.top = self.row + 1 - ROWS;
+        }
+        let column = display_column(self.line(), self.col);
+        if column < self.left {
+            self.left = column;
+        } else if column >= self.left + WIDTH {
+            self.left = column + 1 - WIDTH;
+        }
+    }
+}
+
+impl App for Editor {
+    fn key(&mut self, key: char) -> bool {
+        let running = match core::mem::replace(&mut self.mode, Mode::Editing) {
+            Mode::Editing => {
+                self.message.clear();
+                self.edit(key)
+            }
+            Mode::Asking(question, mut prompt) => {
+                match prompt.key(key) {
+                    Reply::Pending => self.mode = Mode::Asking(question, prompt),
+                    Reply::Cancelled => self.message = String::from("Cancelled"),
+                    Reply::Entered(answer) => self.answer(question, answer),
+                }
+                true
+            }
+            Mode::ConfirmExit => match key.to_ascii_lowercase() {
+                'y' => {
+                    let path = self.path.clone();
+                    !self.save(&path)
+                }
+                'n' => false,
+                Key::CANCEL | Key::ESC | INTERRUPT => {
+                    self.message = String::from("Cancelled");
+                    true
+                }
+                _ => {
+                    self.mode = Mode::ConfirmExit;
+                    true
+                }
+            },
+        };
+        self.scroll();
+        running
+    }
+
+    fn draw(&self, out: &mut String) {
+        screen::title(out, "edit", &self.path, self.modified);
+        for (index, line) in self.lines[self.top..].iter().take(ROWS).enumerate() {
+            let mut text = String::new();
+            for &c in line {
+                if c == '\t' {
+                    let pad = TAB_WIDTH - text.len() % TAB_WIDTH;
+                    text.extend(core::iter::repeat(' ').take(pad));
+                } else {
+                    text.push(screen::printable(c));
+                }
+            }
+            screen::at(out, index + 1, 0);
+            out.extend(text.chars().skip(self.left).take(WIDTH));
+        }
+        screen::shortcuts(out, SHORTCUTS);
+        match &self.mode {
+            Mode::Asking(_, prompt) => prompt.draw(out),
+            Mode::ConfirmExit => {
+                screen::bar(out, STATUS_ROW, "Save modified buffer?  Y Yes  N No  ^G Cancel");
+            }
+            Mode::Editing => {
+                screen::status(out, &self.message);
+                let column = display_column(self.line(), self.col) - self.left;
+                screen::at(out, self.row - self.top + 1, column);
+            }
+        }
+    }
+}
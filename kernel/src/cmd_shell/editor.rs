@@ -0,0 +1,423 @@
+// Line editing for the shell
+//
+// Keys arrive one character at a time, from the PS/2 keyboard or a serial
+// terminal; cursor keys come as VT100 escape sequences from both. The line
+// is redrawn from the start of the row after each change, so a line longer
+// than the screen is wide does not redraw cleanly.
+//
+// History holds the lines entered, oldest first. Up and down recall them,
+// keeping the line being typed to come back to, and Ctrl+R searches them
+// backwards for text typed after it. Tab completes the word before the
+// cursor: a command name first on the line, a path elsewhere.
+//
+// Text killed goes onto the system clipboard, unless the line is masked,
+// and Ctrl+V types the first line of the clipboard's text.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::fs::vfs::VFS;
+use crate::fs::FileType;
+
+/// Lines kept in the history
+pub const HISTORY_SIZE: usize = 500;
+
+/// Control keys, as a terminal sends them
+pub const fn ctrl(key: char) -> char {
+    (key as u8 & 0x1f) as char
+}
+
+enum Escape {
+    Idle,
+    // ESC seen
+    Started,
+    // ESC [ or ESC O, and what has come since
+    Sequence(String),
+}
+
+/// Folds the escape sequences of cursor keys into the control keys in
+/// `Key` that stand for them
+pub struct Escapes(Escape);
+
+impl Escapes {
+    pub const fn new() -> Self {
+        Escapes(Escape::Idle)
+    }
+
+    /// The keys `key` completes: none while a sequence is incomplete, and
+    /// two for a lone ESC followed by another key
+    pub fn feed(&mut self, key: char) -> [Option<char>; 2] {
+        match core::mem::replace(&mut self.0, Escape::Idle) {
+            Escape::Idle if key == Key::ESC => {
+                self.0 = Escape::Started;
+                [None, None]
+            }
+            Escape::Idle => [Some(key), None],
+            Escape::Started if key == '[' || key == 'O' => {
+                self.0 = Escape::Sequence(String::new());
+                [None, None]
+            }
+            Escape::Started if key == Key::ESC => {
+                self.0 = Escape::Started;
+                [Some(Key::ESC), None]
+            }
+            Escape::Started => [Some(Key::ESC), Some(key)],
+            Escape::Sequence(mut sequence) => {
+                if key.is_ascii_digit() || key == ';' {
+                    sequence.push(key);
+                    self.0 = Escape::Sequence(sequence);
+                    return [None, None];
+                }
+                let key = match (sequence.as_str(), key) {
+                    (_, 'A') => Key::UP,
+                    (_, 'B') => Key::DOWN,
+                    (_, 'C') => Key::RIGHT,
+                    (_, 'D') => Key::LEFT,
+                    (_, 'H') | ("1" | "7", '~') => Key::HOME,
+                    (_, 'F') | ("4" | "8", '~') => Key::END,
+                    ("3", '~') => Key::DELETE,
+                    ("5", '~') => Key::PAGE_UP,
+                    ("6", '~') => Key::PAGE_DOWN,
+                    _ => return [None, None],
+                };
+                [Some(key), None]
+            }
+        }
+    }
+}
+
+struct Search {
+    query: String,
+    // The history entry matched
+    found: Option<usize>,
+    // The line as it was before the search, to go back to
+    original: String,
+}
+
+pub struct LineEditor {
+    line: String,
+    cursor: usize,
+    // Characters drawn after the prompt last time, to erase what is left
+    shown: usize,
+    history: Vec<String>,
+    // The entry recalled; history.len() for the line being typed
+    recall: usize,
+    // The line being typed when recalling began
+    draft: String,
+    search: Option<Search>,
+    escapes: Escapes,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            line: String::new(),
+            cursor: 0,
+            shown: 0,
+            history: Vec::new(),
+            recall: 0,
+            draft: String::new(),
+            search: None,
+            escapes: Escapes::new(),
+        }
+    }
+
+    /// Forget the line being typed, once it has been run or abandoned
+    pub fn clear(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.shown = 0;
+        self.recall = self.history.len();
+        self.search = None;
+        self.escapes = Escapes::new();
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    pub fn set_history(&mut self, lines: Vec<String>) {
+        self.history = lines;
+        let excess = self.history.len().saturating_sub(HISTORY_SIZE);
+        self.history.drain(..excess);
+        self.recall = self.history.len();
+    }
+
+    /// Add a line to the history, unless it repeats the last one
+    pub fn remember(&mut self, line: &str) {
+        if self.history.last().map(String::as_str) != Some(line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.remove(0);
+            }
+            self.history.push(String::from(line));
+        }
+        self.recall = self.history.len();
+    }
+
+    /// Draw the prompt and the line on a fresh row, after other output
+    pub fn redisplay(&mut self, prompt: &str, masked: bool) {
+        self.shown = 0;
+        self.redraw(prompt, masked);
+    }
+
+    // Redraw `prompt` and the line from the start of the row, erasing
+    // anything left of the last drawing, and put the cursor back in place
+    fn draw(&mut self, prompt: &str, masked: bool) {
+        let text = if masked { "*".repeat(self.line.len()) } else { self.line.clone() };
+        let width = prompt.len() + text.len();
+        let erase = self.shown.saturating_sub(width);
+        print!("\r{}{}{}", prompt, text, " ".repeat(erase));
+        print!("{}", "\x08".repeat(erase + text.len() - self.cursor));
+        self.shown = width;
+        crate::vga_buffer::follow_cursor();
+    }
+
+    fn redraw(&mut self, prompt: &str, masked: bool) {
+        match &self.search {
+            Some(search) => {
+                let prompt = format!("(reverse-i-search)'{}': ", search.query);
+                self.draw(&prompt, masked);
+            }
+            None => self.draw(prompt, masked),
+        }
+    }
+
+    /// Handle one key. Returns the line when Enter is pressed.
+    ///
+    /// `prompt` is redrawn with the line; `masked` hides the line, as for a
+    /// password, and turns off history and completion. `commands` are the
+    /// names Tab completes first on a line.
+    pub fn key(&mut self, key: char, prompt: &str, masked: bool, commands: &[&str]) -> Option<String> {
+        let mut line = None;
+        for key in self.escapes.feed(key).into_iter().flatten() {
+            line = line.or(self.edit(key, prompt, masked, commands));
+        }
+        line
+    }
+
+    fn edit(&mut self, key: char, prompt: &str, masked: bool, commands: &[&str]) -> Option<String> {
+        if self.search.is_some() && self.search_key(key, prompt, masked) {
+            return None;
+        }
+
+        match key {
+            '\n' => return Some(core::mem::take(&mut self.line)),
+            '\x08' | '\x7f' if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::DELETE if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::LEFT if self.cursor > 0 => self.cursor -= 1,
+            Key::RIGHT if self.cursor < self.line.len() => self.cursor += 1,
+            Key::HOME => self.cursor = 0,
+            Key::END => self.cursor = self.line.len(),
+            Key::UP if !masked => self.recall_entry(self.recall.checked_sub(1)),
+            Key::DOWN if !masked => self.recall_entry(Some(self.recall + 1)),
+            // Kill to the end, to the start, and the word before the cursor
+            Key::KILL_END => self.kill(self.cursor..self.line.len(), masked),
+            Key::KILL_START => {
+                self.kill(0..self.cursor, masked);
+                self.cursor = 0;
+            }
+            Key::KILL_WORD => {
+                let start = self.line[..self.cursor].trim_end().rfind(' ').map_or(0, |space| space + 1);
+                self.kill(start..self.cursor, masked);
+                self.cursor = start;
+            }
+            Key::PASTE => self.paste(),
+            Key::SEARCH if !masked => {
+                self.search = Some(Search { query: String::new(), found: None, original: self.line.clone() });
+            }
+            '\t' if !masked => self.complete(commands),
+            key if key.is_ascii() && !key.is_control() && self.line.len() < super::MAX_COMMAND_LENGTH => {
+                self.line.insert(self.cursor, key);
+                self.cursor += 1;
+                // Typing at the end only needs the key echoed
+                if self.cursor == self.line.len() && self.shown == prompt.len() + self.line.len() - 1 {
+                    print!("{}", if masked { '*' } else { key });
+                    self.shown += 1;
+                    crate::vga_buffer::follow_cursor();
+                    return None;
+                }
+            }
+            _ => {}
+        }
+        self.redraw(prompt, masked);
+        None
+    }
+
+    // Take text out of the line, onto the clipboard unless it is hidden
+    fn kill(&mut self, range: Range<usize>, masked: bool) {
+        let killed: String = self.line.drain(range).collect();
+        if !masked && !killed.is_empty() {
+            crate::clipboard::set_text(&killed);
+        }
+    }
+
+    // Type the first line of the clipboard's text at the cursor, as much
+    // as fits
+    fn paste(&mut self) {
+        let Some(text) = crate::clipboard::text() else { return };
+        let line = text.lines().next().unwrap_or("");
+        for c in line.chars().filter(|c| c.is_ascii() && !c.is_control()) {
+            if self.line.len() >= super::MAX_COMMAND_LENGTH {
+                break;
+            }
+            self.line.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    // Show history entry `index`, or the line that was being typed past the
+    // newest
+    fn recall_entry(&mut self, index: Option<usize>) {
+        let Some(index) = index.filter(|&index| index <= self.history.len()) else { return };
+        if self.recall == self.history.len() {
+            self.draft = self.line.clone();
+        }
+        self.recall = index;
+        self.line = match self.history.get(index) {
+            Some(entry) => entry.clone(),
+            None => self.draft.clone(),
+        };
+        self.cursor = self.line.len();
+    }
+
+    fn cancel_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            self.line = search.original;
+            self.cursor = self.line.len();
+            self.recall = self.history.len();
+        }
+    }
+
+    // A key typed during a search; false if it ends the search and should
+    // then be handled as usual, on the match
+    fn search_key(&mut self, key: char, prompt: &str, masked: bool) -> bool {
+        let search = self.search.as_mut().unwrap();
+        let from = match key {
+            // Again: the next older match
+            Key::SEARCH => search.found.unwrap_or(self.history.len()),
+            '\x08' | '\x7f' => {
+                search.query.pop();
+                self.history.len()
+            }
+            // Give up, back to the line as it was
+            Key::CANCEL | Key::ESC => {
+                self.cancel_search();
+                self.redraw(prompt, masked);
+                return true;
+            }
+            key if key.is_ascii() && !key.is_control() => {
+                search.query.push(key);
+                search.found.map_or(self.history.len(), |found| found + 1)
+            }
+            _ => {
+                // Keep the match and edit it, or run it with Enter
+                self.search = None;
+                self.redraw(prompt, masked);
+                return false;
+            }
+        };
+        let query = search.query.clone();
+        let found = self.history[..from.min(self.history.len())].iter().rposition(|entry| entry.contains(query.as_str()));
+        if let Some(index) = found {
+            search.found = Some(index);
+            self.line = self.history[index].clone();
+            self.cursor = self.line.find(query.as_str()).unwrap_or(0);
+            self.recall = index;
+        }
+        self.redraw(prompt, masked);
+        true
+    }
+
+    // Complete the word before the cursor, or as much of it as all the
+    // candidates share; list them if that adds nothing
+    fn complete(&mut self, commands: &[&str]) {
+        let start = self.line[..self.cursor].rfind([' ', '|', '<', '>', '&']).map_or(0, |index| index + 1);
+        let word = &self.line[start..self.cursor];
+        let first = self.line[..start].trim_end().is_empty() || self.line[..start].trim_end().ends_with('|');
+
+        let mut candidates: Vec<String> = Vec::new();
+        if first && !word.contains('/') {
+            candidates.extend(commands.iter().filter(|name| name.starts_with(word)).map(|name| format!("{} ", name)));
+        }
+        let (directory, prefix, partial) = match word.rfind('/') {
+            Some(slash) => (if slash == 0 { "/" } else { &word[..slash] }, &word[..=slash], &word[slash + 1..]),
+            None => ("/", "", word),
+        };
+        if let Ok(files) = VFS.lock().list_directory(directory) {
+            for file in files {
+                if file.name.to_ascii_lowercase().starts_with(&partial.to_ascii_lowercase()) {
+                    let end = if matches!(file.file_type, FileType::Directory) { "/" } else { " " };
+                    candidates.push(format!("{}{}{}", prefix, file.name, end));
+                }
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+
+        let Some(first_candidate) = candidates.first() else { return };
+        let mut shared = first_candidate.len();
+        for candidate in &candidates[1..] {
+            shared = first_candidate
+                .bytes()
+                .zip(candidate.bytes())
+                .take(shared)
+                .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+                .count();
+        }
+        if candidates.len() > 1 {
+            // The shared part would leave a trailing space or slash off
+            shared = shared.min(candidates.iter().map(|candidate| candidate.len() - 1).min().unwrap_or(0));
+        }
+        if shared > word.len() {
+            let completion = String::from(&first_candidate[..shared]);
+            self.line.replace_range(start..self.cursor, &completion);
+            self.cursor = start + completion.len();
+            if self.line.len() > super::MAX_COMMAND_LENGTH {
+                self.line.truncate(super::MAX_COMMAND_LENGTH);
+                self.cursor = self.cursor.min(self.line.len());
+            }
+            return;
+        }
+        if candidates.len() > 1 {
+            print!("\n");
+            for candidate in &candidates {
+                print!("{}  ", candidate.trim_end());
+            }
+            print!("\n");
+            self.shown = 0;
+        }
+    }
+}
+
+/// Keys the editors act on, as control characters and as the escape
+/// sequences folded into them
+pub struct Key;
+
+impl Key {
+    pub const ESC: char = '\x1b';
+    pub const HOME: char = ctrl('a');
+    pub const LEFT: char = ctrl('b');
+    pub const DELETE: char = ctrl('d');
+    pub const END: char = ctrl('e');
+    pub const RIGHT: char = ctrl('f');
+    pub const CANCEL: char = ctrl('g');
+    pub const KILL_END: char = ctrl('k');
+    pub const DOWN: char = ctrl('n');
+    pub const UP: char = ctrl('p');
+    pub const SEARCH: char = ctrl('r');
+    pub const KILL_START: char = ctrl('u');
+    pub const KILL_WORD: char = ctrl('w');
+    // As in nano
+    pub const PAGE_UP: char = ctrl('y');
+    pub const PAGE_DOWN: char = ctrl('v');
+    // Ctrl+V, as in the Windows console. Page Down comes as the same key,
+    // which the line editor has no other use for.
+    pub const PASTE: char = ctrl('v');
+}
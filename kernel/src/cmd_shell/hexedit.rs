@@ -0,0 +1,276 @@
+// `hexedit`: a full-screen hex editor, and `hexdump`'s format
+//
+// Rows show 16 bytes, as `hexdump -C` does: the offset, the bytes in hex,
+// and the printable ones as text. Typing overwrites: hex digits in the hex
+// column, a nibble at a time, and characters in the text column. Typing at
+// the end of the file adds to it; nothing can be inserted or removed.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::editor::{ctrl, Key};
+use super::screen::{self, App, Prompt, Reply, EXIT, INTERRUPT, ROWS, SAVE, STATUS_ROW, WRITE_OUT};
+use crate::fs::vfs::VFS;
+use crate::fs::FileSystemError;
+
+pub const BYTES_PER_ROW: usize = 16;
+
+const GO_TO: char = ctrl('t');
+
+const SHORTCUTS: &[(&str, &str)] = &[
+    ("^O", "Write Out"),
+    ("^X", "Exit"),
+    ("^S", "Save"),
+    ("^T", "Go To"),
+    ("Tab", "Hex/Text"),
+    ("^C", "Cur Pos"),
+    ("^Y", "Prev Page"),
+    ("^V", "Next Page"),
+];
+
+// Where the hex and text columns start on a row
+const HEX_COLUMN: usize = 10;
+const TEXT_COLUMN: usize = 61;
+
+/// One row in `hexdump -C`'s format, for the bytes at `offset`
+pub fn row(offset: usize, bytes: &[u8]) -> String {
+    let mut text = format!("{:08x}  ", offset);
+    for index in 0..BYTES_PER_ROW {
+        match bytes.get(index) {
+            Some(byte) => text += &format!("{:02x} ", byte),
+            None => text += "   ",
+        }
+        if index == BYTES_PER_ROW / 2 - 1 {
+            text.push(' ');
+        }
+    }
+    text += " |";
+    text.extend(bytes.iter().map(|&byte| screen::printable(byte as char)));
+    text.push('|');
+    text
+}
+
+/// Parse an offset: hex with 0x, or decimal
+pub fn parse_offset(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+enum Question {
+    WriteOut,
+    GoTo,
+}
+
+enum Mode {
+    Editing,
+    Asking(Question, Prompt),
+    ConfirmExit,
+}
+
+pub struct HexEditor {
+    path: String,
+    data: Vec<u8>,
+    modified: bool,
+    // The byte under the cursor, which may be one past the end
+    cursor: usize,
+    // The low nibble, in the hex column
+    low: bool,
+    // In the text column rather than the hex
+    text: bool,
+    // The first row shown
+    top: usize,
+    mode: Mode,
+    message: String,
+}
+
+impl HexEditor {
+    /// Open `path`, or start a new file there
+    pub fn open(path: &str) -> Result<Self, String> {
+        let data = VFS.lock().read_file(path);
+        let (data, message) = match data {
+            Ok(data) => {
+                let message = format!("Read {} bytes", data.len());
+                (data, message)
+            }
+            Err(FileSystemError::NotFound) => (Vec::new(), String::from("New File")),
+            Err(FileSystemError::PermissionDenied) => return Err(format!("{}: access denied", path)),
+            Err(error) => return Err(format!("Cannot read {}: {:?}", path, error)),
+        };
+        Ok(HexEditor {
+            path: String::from(path),
+            data,
+            modified: false,
+            cursor: 0,
+            low: false,
+            text: false,
+            top: 0,
+            mode: Mode::Editing,
+            message,
+        })
+    }
+
+    fn save(&mut self, path: &str) -> bool {
+        match VFS.lock().write_file(path, &self.data) {
+            Ok(()) => {
+                self.path = String::from(path);
+                self.modified = false;
+                self.message = format!("Wrote {} bytes", self.data.len());
+                true
+            }
+            Err(error) => {
+                self.message = format!("Error writing {}: {:?}", path, error);
+                false
+            }
+        }
+    }
+
+    // Write the byte under the cursor, adding it if past the end
+    fn set(&mut self, byte: impl FnOnce(u8) -> u8) {
+        if self.cursor == self.data.len() {
+            self.data.push(0);
+        }
+        self.data[self.cursor] = byte(self.data[self.cursor]);
+        self.modified = true;
+    }
+
+    fn move_to(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.data.len());
+        self.low = false;
+    }
+
+    fn answer(&mut self, question: Question, answer: String) {
+        match question {
+            Question::WriteOut if answer.is_empty() => self.message = String::from("Cancelled"),
+            Question::WriteOut => {
+                self.save(&answer);
+            }
+            Question::GoTo => match parse_offset(answer.trim()) {
+                Some(offset) if offset <= self.data.len() => self.move_to(offset),
+                Some(_) => self.message = format!("The file is {:#x} bytes", self.data.len()),
+                None => self.message = String::from("Not an offset; give hex with 0x, or decimal"),
+            },
+        }
+    }
+
+    fn edit(&mut self, key: char) -> bool {
+        match key {
+            EXIT if self.modified => self.mode = Mode::ConfirmExit,
+            EXIT => return false,
+            WRITE_OUT => self.mode = Mode::Asking(Question::WriteOut, Prompt::new("File Name to Write", self.path.clone())),
+            SAVE => {
+                let path = self.path.clone();
+                self.save(&path);
+            }
+            GO_TO => self.mode = Mode::Asking(Question::GoTo, Prompt::new("Go to offset", String::new())),
+            INTERRUPT => self.message = format!("offset {:#x} of {:#x}", self.cursor, self.data.len()),
+            '\t' => {
+                self.text = !self.text;
+                self.low = false;
+            }
+            Key::LEFT if self.low => self.low = false,
+            Key::LEFT => self.move_to(self.cursor.saturating_sub(1)),
+            Key::RIGHT => self.move_to(self.cursor + 1),
+            Key::UP => self.move_to(self.cursor.saturating_sub(BYTES_PER_ROW)),
+            Key::DOWN if self.cursor + BYTES_PER_ROW <= self.data.len() => self.move_to(self.cursor + BYTES_PER_ROW),
+            Key::PAGE_UP => self.move_to(self.cursor.saturating_sub(ROWS * BYTES_PER_ROW)),
+            Key::PAGE_DOWN => self.move_to(self.cursor + ROWS * BYTES_PER_ROW),
+            Key::HOME => self.move_to(self.cursor - self.cursor % BYTES_PER_ROW),
+            Key::END => self.move_to(self.cursor - self.cursor % BYTES_PER_ROW + BYTES_PER_ROW - 1),
+            key if self.text && key.is_ascii() && !key.is_control() => {
+                self.set(|_| key as u8);
+                self.move_to(self.cursor + 1);
+            }
+            key if !self.text && key.is_ascii_hexdigit() => {
+                let nibble = key.to_digit(16).unwrap() as u8;
+                if self.low {
+                    self.set(|byte| byte & 0xf0 | nibble);
+                    self.move_to(self.cursor + 1);
+                } else {
+                    self.set(|byte| byte & 0x0f | nibble << 4);
+                    self.low = true;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn scroll(&mut self) {
+        let row = self.cursor / BYTES_PER_ROW;
+        if row < self.top {
+            self.top = row;
+        } else if row >= self.top + ROWS {
+            self.top = row + 1 - ROWS;
+        }
+    }
+}
+
+impl App for HexEditor {
+    fn key(&mut self, key: char) -> bool {
+        let running = match core::mem::replace(&mut self.mode, Mode::Editing) {
+            Mode::Editing => {
+                self.message.clear();
+                self.edit(key)
+            }
+            Mode::Asking(question, mut prompt) => {
+                match prompt.key(key) {
+                    Reply::Pending => self.mode = Mode::Asking(question, prompt),
+                    Reply::Cancelled => self.message = String::from("Cancelled"),
+                    Reply::Entered(answer) => self.answer(question, answer),
+                }
+                true
+            }
+            Mode::ConfirmExit => match key.to_ascii_lowercase() {
+                'y' => {
+                    let path = self.path.clone();
+                    !self.save(&path)
+                }
+                'n' => false,
+                Key::CANCEL | Key::ESC | INTERRUPT => {
+                    self.message = String::from("Cancelled");
+                    true
+                }
+                _ => {
+                    self.mode = Mode::ConfirmExit;
+                    true
+                }
+            },
+        };
+        self.scroll();
+        running
+    }
+
+    fn draw(&self, out: &mut String) {
+        screen::title(out, "hexedit", &self.path, self.modified);
+        for index in 0..ROWS {
+            let offset = (self.top + index) * BYTES_PER_ROW;
+            // The row after the last byte is shown, to add to the file on
+            if offset > self.data.len() {
+                break;
+            }
+            let end = (offset + BYTES_PER_ROW).min(self.data.len());
+            screen::at(out, index + 1, 0);
+            *out += &row(offset, &self.data[offset..end]);
+        }
+        screen::shortcuts(out, SHORTCUTS);
+        match &self.mode {
+            Mode::Asking(_, prompt) => prompt.draw(out),
+            Mode::ConfirmExit => {
+                screen::bar(out, STATUS_ROW, "Save modified buffer?  Y Yes  N No  ^G Cancel");
+            }
+            Mode::Editing => {
+                screen::status(out, &self.message);
+                let column = self.cursor % BYTES_PER_ROW;
+                let column = if self.text {
+                    TEXT_COLUMN + column
+                } else {
+                    HEX_COLUMN + column * 3 + column / (BYTES_PER_ROW / 2) + self.low as usize
+                };
+                screen::at(out, self.cursor / BYTES_PER_ROW - self.top + 1, column);
+            }
+        }
+    }
+}
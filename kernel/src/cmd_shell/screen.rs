@@ -0,0 +1,179 @@
+// Full-screen commands
+//
+// `edit` and `hexedit` take the display over until they exit. Each draws
+// its whole screen as VT100 output into a console screen buffer, which is
+// copied to the text-mode framebuffer after every key. The shell's screen
+// is saved when one starts and put back when it exits. Meanwhile keys go to
+// it instead of the line editor, with cursor keys folded the same way.
+//
+// Both follow nano: a title bar, the text, a status line for messages and
+// questions, and two rows of shortcuts.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::editor::{ctrl, Escapes, Key};
+use crate::vga_buffer::{TEXT_HEIGHT, TEXT_WIDTH};
+use crate::win32::console::{
+    ConsoleWindowInfo, ScreenBuffer, ENABLE_PROCESSED_OUTPUT, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+};
+
+pub const WIDTH: usize = TEXT_WIDTH;
+/// Rows between the title bar and the status line
+pub const ROWS: usize = TEXT_HEIGHT - 4;
+pub const STATUS_ROW: usize = TEXT_HEIGHT - 2;
+
+const WINDOW: ConsoleWindowInfo = ConsoleWindowInfo {
+    left: 0,
+    top: 0,
+    right: TEXT_WIDTH as i16 - 1,
+    bottom: TEXT_HEIGHT as i16 - 1,
+};
+
+pub const INTERRUPT: char = ctrl('c');
+pub const WRITE_OUT: char = ctrl('o');
+pub const SAVE: char = ctrl('s');
+pub const EXIT: char = ctrl('x');
+
+pub trait App {
+    /// Handle a key; false once the command has exited
+    fn key(&mut self, key: char) -> bool;
+    /// Draw the whole screen, leaving the cursor where it belongs
+    fn draw(&self, out: &mut String);
+}
+
+pub struct Screen {
+    app: Box<dyn App>,
+    buffer: ScreenBuffer,
+    escapes: Escapes,
+    // The shell's screen, to put back
+    saved: Vec<(u8, u8)>,
+}
+
+impl Screen {
+    pub fn open(app: Box<dyn App>) -> Self {
+        let mut screen = Screen {
+            app,
+            buffer: ScreenBuffer::new(
+                TEXT_WIDTH as u16,
+                TEXT_HEIGHT as u16,
+                ENABLE_PROCESSED_OUTPUT | ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+            ),
+            escapes: Escapes::new(),
+            saved: crate::vga_buffer::read_cells(),
+        };
+        screen.draw();
+        screen
+    }
+
+    /// Handle a key; false once the command has exited, and the screen
+    /// should be closed
+    pub fn key(&mut self, key: char) -> bool {
+        for key in self.escapes.feed(key).into_iter().flatten() {
+            if !self.app.key(key) {
+                return false;
+            }
+        }
+        self.draw();
+        true
+    }
+
+    fn draw(&mut self) {
+        let mut out = String::from("\x1b[0m\x1b[2J");
+        self.app.draw(&mut out);
+        self.buffer.write(out.as_bytes());
+        self.buffer.present(&WINDOW);
+    }
+
+    /// Give the shell its screen back
+    pub fn close(self) {
+        crate::vga_buffer::blit_cells(&self.saved, TEXT_WIDTH);
+        crate::vga_buffer::follow_cursor();
+    }
+}
+
+/// Move to a row and column, counted from 0
+pub fn at(out: &mut String, row: usize, col: usize) {
+    *out += &format!("\x1b[{};{}H", row + 1, col + 1);
+}
+
+/// A row in reverse video, as the title and status bars are
+pub fn bar(out: &mut String, row: usize, text: &str) {
+    at(out, row, 0);
+    *out += &format!("\x1b[7m{:<width$.width$}\x1b[0m", text, width = WIDTH);
+}
+
+/// The title bar: the command, the file, and whether it has changed
+pub fn title(out: &mut String, command: &str, path: &str, modified: bool) {
+    let state = if modified { "Modified" } else { "" };
+    let text = format!("  {}  {}", command, path);
+    bar(out, 0, &format!("{:<width$}{:>8}  ", text, state, width = WIDTH - 10));
+}
+
+/// The status line, reversed when there is something in it
+pub fn status(out: &mut String, message: &str) {
+    if message.is_empty() {
+        return;
+    }
+    let text = format!("[ {} ]", message);
+    at(out, STATUS_ROW, WIDTH.saturating_sub(text.len()) / 2);
+    *out += &format!("\x1b[7m{:.width$}\x1b[0m", text, width = WIDTH);
+}
+
+/// The two rows of shortcuts at the bottom, each a key and what it does
+pub fn shortcuts(out: &mut String, keys: &[(&str, &str)]) {
+    let columns = keys.len().div_ceil(2);
+    let width = WIDTH / columns.max(1);
+    for (index, (key, action)) in keys.iter().enumerate() {
+        at(out, STATUS_ROW + 1 + index % 2, index / 2 * width);
+        *out += &format!("\x1b[7m{}\x1b[0m {:.width$}", key, action, width = width.saturating_sub(key.len() + 2));
+    }
+}
+
+/// Something printable to show for a character
+pub fn printable(c: char) -> char {
+    if c.is_ascii_graphic() || c == ' ' {
+        c
+    } else {
+        '.'
+    }
+}
+
+pub enum Reply {
+    Pending,
+    Cancelled,
+    Entered(String),
+}
+
+/// A question on the status line, with a line of text for its answer
+pub struct Prompt {
+    question: &'static str,
+    answer: String,
+}
+
+impl Prompt {
+    pub fn new(question: &'static str, answer: String) -> Self {
+        Prompt { question, answer }
+    }
+
+    pub fn key(&mut self, key: char) -> Reply {
+        match key {
+            '\n' => return Reply::Entered(core::mem::take(&mut self.answer)),
+            Key::CANCEL | Key::ESC | INTERRUPT => return Reply::Cancelled,
+            '\x08' | '\x7f' => {
+                self.answer.pop();
+            }
+            key if key.is_ascii() && !key.is_control() && self.answer.len() < WIDTH / 2 => self.answer.push(key),
+            _ => {}
+        }
+        Reply::Pending
+    }
+
+    pub fn draw(&self, out: &mut String) {
+        let text = format!("{}: {}", self.question, self.answer);
+        bar(out, STATUS_ROW, &text);
+        at(out, STATUS_ROW, text.len().min(WIDTH - 1));
+    }
+}
@@ -0,0 +1,320 @@
+//! Hierarchical resource groups
+//!
+//! Groups form a tree under the root group, id 0, which holds every process
+//! not placed elsewhere. Processes join a group and their children start in
+//! it. A limit covers the group and everything below it together:
+//!
+//! - CPU shares weigh the scheduler quantum of member processes against the
+//!   default of 1024, multiplied along the path from the root.
+//! - Memory limits the user memory (heap and mappings) of the members,
+//!   charged when brk or mmap grows it.
+//! - I/O bandwidth limits bytes per second of file reads and writes. Each
+//!   transfer pushes back the time the group's budget frees up, and the next
+//!   one waits for it.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::ContainerError;
+use crate::sync::Mutex;
+
+pub const DEFAULT_CPU_SHARES: u32 = 1024;
+const MIN_CPU_SHARES: u32 = 2;
+const MAX_CPU_SHARES: u32 = 262_144;
+
+// Bounds of a scaled quantum, in timer ticks
+const MIN_SLICE: u32 = 1;
+const MAX_SLICE: u32 = 100;
+
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    CpuShares,
+    /// Bytes, 0 for no limit
+    MemoryLimit,
+    /// Bytes per second, 0 for no limit
+    IoBandwidth,
+}
+
+impl Resource {
+    pub fn from_raw(raw: usize) -> Option<Self> {
+        match raw {
+            0 => Some(Resource::CpuShares),
+            1 => Some(Resource::MemoryLimit),
+            2 => Some(Resource::IoBandwidth),
+            _ => None,
+        }
+    }
+}
+
+struct Group {
+    name: String,
+    parent: u32,
+    cpu_shares: u32,
+    memory_limit: Option<u64>,
+    io_bandwidth: Option<u64>,
+    // TSC value at which the I/O budget has caught up with past transfers
+    io_free_at: u64,
+    cpu_ticks: u64,
+    io_bytes: u64,
+}
+
+struct Groups {
+    next_id: u32,
+    groups: BTreeMap<u32, Group>,
+    // Processes outside this map are in the root group
+    members: BTreeMap<u32, u32>,
+    // User memory charged to each process
+    memory: BTreeMap<u32, u64>,
+}
+
+static GROUPS: Mutex<Groups> = Mutex::new(Groups {
+    next_id: 1,
+    groups: BTreeMap::new(),
+    members: BTreeMap::new(),
+    memory: BTreeMap::new(),
+});
+
+// Calibrated when the first bandwidth limit is set
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+impl Groups {
+    fn group_of(&self, pid: u32) -> u32 {
+        self.members.get(&pid).copied().unwrap_or(0)
+    }
+
+    // A group followed by its ancestors, the root excluded
+    fn chain(&self, mut id: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        while let Some(group) = self.groups.get(&id) {
+            chain.push(id);
+            id = group.parent;
+        }
+        chain
+    }
+
+    // Memory charged to each group, its descendants included
+    fn memory_usage(&self) -> BTreeMap<u32, u64> {
+        let mut usage = BTreeMap::new();
+        for (&pid, &bytes) in &self.memory {
+            for id in self.chain(self.group_of(pid)) {
+                *usage.entry(id).or_insert(0) += bytes;
+            }
+        }
+        usage
+    }
+}
+
+/// Create a group below `parent`, returning its id
+pub fn create(parent: u32, name: &str) -> Result<u32, ContainerError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+        return Err(ContainerError::InvalidConfig);
+    }
+    let mut groups = GROUPS.lock();
+    if parent != 0 && !groups.groups.contains_key(&parent) {
+        return Err(ContainerError::NotFound);
+    }
+    if groups.groups.values().any(|group| group.parent == parent && group.name == name) {
+        return Err(ContainerError::InvalidConfig);
+    }
+
+    let id = groups.next_id;
+    groups.next_id += 1;
+    groups.groups.insert(
+        id,
+        Group {
+            name: name.to_string(),
+            parent,
+            cpu_shares: DEFAULT_CPU_SHARES,
+            memory_limit: None,
+            io_bandwidth: None,
+            io_free_at: 0,
+            cpu_ticks: 0,
+            io_bytes: 0,
+        },
+    );
+    Ok(id)
+}
+
+/// Remove a group with no processes and no groups below it
+pub fn destroy(id: u32) -> Result<(), ContainerError> {
+    let mut groups = GROUPS.lock();
+    if !groups.groups.contains_key(&id) {
+        return Err(ContainerError::NotFound);
+    }
+    let busy = groups.members.values().any(|&group| group == id)
+        || groups.groups.values().any(|group| group.parent == id);
+    if busy {
+        return Err(ContainerError::InvalidState);
+    }
+    groups.groups.remove(&id);
+    Ok(())
+}
+
+pub fn set(id: u32, resource: Resource, value: u64) -> Result<(), ContainerError> {
+    if resource == Resource::IoBandwidth && value != 0 && TSC_FREQUENCY.load(Ordering::Relaxed) == 0 {
+        TSC_FREQUENCY.store(crate::timer::get_tsc_frequency().max(1), Ordering::Relaxed);
+    }
+
+    let mut groups = GROUPS.lock();
+    let group = groups.groups.get_mut(&id).ok_or(ContainerError::NotFound)?;
+    let limit = (value != 0).then_some(value);
+    match resource {
+        Resource::CpuShares => {
+            if !(MIN_CPU_SHARES as u64..=MAX_CPU_SHARES as u64).contains(&value) {
+                return Err(ContainerError::InvalidConfig);
+            }
+            group.cpu_shares = value as u32;
+        }
+        Resource::MemoryLimit => group.memory_limit = limit,
+        Resource::IoBandwidth => group.io_bandwidth = limit,
+    }
+    Ok(())
+}
+
+/// Move a process into a group; 0 is the root group
+pub fn join(pid: u32, id: u32) -> Result<(), ContainerError> {
+    let mut groups = GROUPS.lock();
+    if id == 0 {
+        groups.members.remove(&pid);
+        return Ok(());
+    }
+    if !groups.groups.contains_key(&id) {
+        return Err(ContainerError::NotFound);
+    }
+    groups.members.insert(pid, id);
+    Ok(())
+}
+
+/// Start a new process in its parent's group
+pub fn inherit(parent: u32, child: u32) {
+    let mut groups = GROUPS.lock();
+    let id = groups.group_of(parent);
+    if id != 0 {
+        groups.members.insert(child, id);
+    }
+}
+
+pub fn release_process(pid: u32) {
+    let mut groups = GROUPS.lock();
+    groups.members.remove(&pid);
+    groups.memory.remove(&pid);
+}
+
+/// Record that the process will hold `bytes` of user memory, unless that
+/// takes a group over its limit
+pub fn charge_memory(pid: u32, bytes: u64) -> Result<(), ContainerError> {
+    let mut groups = GROUPS.lock();
+    let previous = groups.memory.get(&pid).copied().unwrap_or(0);
+    let usage = groups.memory_usage();
+    for id in groups.chain(groups.group_of(pid)) {
+        let Some(limit) = groups.groups[&id].memory_limit else {
+            continue;
+        };
+        let used = usage.get(&id).copied().unwrap_or(0) - previous;
+        if used + bytes > limit {
+            return Err(ContainerError::ResourceLimitExceeded);
+        }
+    }
+    groups.memory.insert(pid, bytes);
+    Ok(())
+}
+
+/// Scheduler quantum of a process: `base` ticks weighted by the CPU shares
+/// of its groups. Called from the timer interrupt, so it gives up on a
+/// contended lock rather than spin.
+pub fn time_slice(pid: u32, base: u32) -> u32 {
+    let Some(groups) = GROUPS.try_lock() else {
+        return base;
+    };
+    let mut slice = base as u64;
+    for id in groups.chain(groups.group_of(pid)) {
+        slice = slice * groups.groups[&id].cpu_shares as u64 / DEFAULT_CPU_SHARES as u64;
+    }
+    slice.clamp(MIN_SLICE as u64, MAX_SLICE as u64) as u32
+}
+
+/// Account timer ticks the process ran for; interrupt context, like
+/// `time_slice`
+pub fn charge_cpu(pid: u32, ticks: u64) {
+    let Some(mut groups) = GROUPS.try_lock() else {
+        return;
+    };
+    for id in groups.chain(groups.group_of(pid)) {
+        if let Some(group) = groups.groups.get_mut(&id) {
+            group.cpu_ticks += ticks;
+        }
+    }
+}
+
+/// Wait until the process's groups have I/O budget left
+pub fn throttle_io(pid: u32) {
+    let free_at = {
+        let groups = GROUPS.lock();
+        groups
+            .chain(groups.group_of(pid))
+            .iter()
+            .filter(|id| groups.groups[id].io_bandwidth.is_some())
+            .map(|id| groups.groups[id].io_free_at)
+            .max()
+    };
+    if let Some(free_at) = free_at {
+        while crate::timer::rdtsc() < free_at {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Charge a transfer of `bytes` to the process's groups
+pub fn charge_io(pid: u32, bytes: u64) {
+    let tsc_frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    let now = crate::timer::rdtsc();
+    let mut groups = GROUPS.lock();
+    for id in groups.chain(groups.group_of(pid)) {
+        if let Some(group) = groups.groups.get_mut(&id) {
+            group.io_bytes += bytes;
+            if let Some(bandwidth) = group.io_bandwidth {
+                let cost = (bytes as u128 * tsc_frequency as u128 / bandwidth as u128) as u64;
+                group.io_free_at = group.io_free_at.max(now) + cost;
+            }
+        }
+    }
+}
+
+/// A group's settings and usage, for the shell
+pub struct GroupInfo {
+    pub id: u32,
+    pub parent: u32,
+    pub name: String,
+    pub cpu_shares: u32,
+    pub memory_limit: Option<u64>,
+    pub memory_usage: u64,
+    pub io_bandwidth: Option<u64>,
+    pub io_bytes: u64,
+    pub cpu_ticks: u64,
+    pub processes: usize,
+}
+
+pub fn list() -> Vec<GroupInfo> {
+    let groups = GROUPS.lock();
+    let usage = groups.memory_usage();
+    groups
+        .groups
+        .iter()
+        .map(|(&id, group)| GroupInfo {
+            id,
+            parent: group.parent,
+            name: group.name.clone(),
+            cpu_shares: group.cpu_shares,
+            memory_limit: group.memory_limit,
+            memory_usage: usage.get(&id).copied().unwrap_or(0),
+            io_bandwidth: group.io_bandwidth,
+            io_bytes: group.io_bytes,
+            cpu_ticks: group.cpu_ticks,
+            processes: groups.members.values().filter(|&&member| member == id).count(),
+        })
+        .collect()
+}
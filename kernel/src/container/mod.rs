@@ -7,12 +7,26 @@ use crate::serial_println;
 
 pub mod namespace;
 pub mod cgroup;
+pub mod nsproxy;
+pub mod group;
 
 use namespace::{Namespace, NamespaceType, PidNamespace, NetNamespace, MountNamespace, IpcNamespace, UserNamespace, UtsNamespace};
 use cgroup::{Cgroup, CgroupController};
 
 static CONTAINER_ID_COUNTER: AtomicU32 = AtomicU32::new(1);
 
+/// Start a new process in its parent's namespaces and resource group
+pub fn inherit(parent: u32, child: u32) {
+    nsproxy::inherit(parent, child);
+    group::inherit(parent, child);
+}
+
+/// Drop an exiting process from its namespaces and resource group
+pub fn release_process(pid: u32) {
+    nsproxy::release_process(pid);
+    group::release_process(pid);
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ContainerState {
     Created,
@@ -420,6 +434,7 @@ pub enum ContainerError {
     ResourceLimitExceeded,
     ImageNotFound,
     InvalidConfig,
+    NotFound,
 }
 
 impl core::fmt::Display for ContainerError {
@@ -435,6 +450,7 @@ impl core::fmt::Display for ContainerError {
             Self::ResourceLimitExceeded => write!(f, "Resource limit exceeded"),
             Self::ImageNotFound => write!(f, "Container image not found"),
             Self::InvalidConfig => write!(f, "Invalid container configuration"),
+            Self::NotFound => write!(f, "No such namespace or group"),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,447 @@
+//! Namespaces processes run in
+//!
+//! `namespace` describes what a container is given; this is what the kernel
+//! enforces. Every process has a set of namespace ids, inherited by its
+//! children, with 0 the initial namespace of each kind:
+//!
+//! - PID: a process sees and signals only processes of its own namespace
+//!   and those below it, numbered from 1 in each. As on Linux, unsharing or
+//!   joining a PID namespace places the caller's future children in it, not
+//!   the caller.
+//! - Mount: bind mounts map paths of the namespace's view onto paths of the
+//!   global VFS. Binding over "/" confines the namespace to a subtree.
+//! - Network: sockets bind ports per namespace, and a namespace other than
+//!   the initial one has only loopback.
+//! - UTS: host name.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+use super::ContainerError;
+use crate::fs::security::normalize_path;
+use crate::sync::Mutex;
+
+pub const CLONE_NEWNS: usize = 0x0002_0000;
+pub const CLONE_NEWUTS: usize = 0x0400_0000;
+pub const CLONE_NEWPID: usize = 0x2000_0000;
+pub const CLONE_NEWNET: usize = 0x4000_0000;
+
+const CLONE_NAMESPACES: usize = CLONE_NEWNS | CLONE_NEWUTS | CLONE_NEWPID | CLONE_NEWNET;
+
+// Nesting limit of PID namespaces, as on Linux
+const MAX_PID_LEVEL: u32 = 32;
+
+pub const MAX_HOSTNAME_LEN: usize = 64;
+const DEFAULT_HOSTNAME: &str = "rustos";
+
+/// Namespace ids of one process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NsSet {
+    pub pid: u32,
+    /// PID namespace new children are created in
+    pub pid_for_children: u32,
+    pub mount: u32,
+    pub net: u32,
+    pub uts: u32,
+}
+
+struct PidNamespace {
+    parent: u32,
+    level: u32,
+    next_pid: u32,
+    to_host: BTreeMap<u32, u32>,
+    from_host: BTreeMap<u32, u32>,
+}
+
+struct MountNamespace {
+    // (target in the namespace's view, source in the global VFS), longest
+    // target first so the innermost bind wins
+    binds: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+struct UtsNamespace {
+    hostname: String,
+}
+
+struct Namespaces {
+    next_id: u32,
+    // Processes outside this map are in the initial namespaces
+    processes: BTreeMap<u32, NsSet>,
+    pid: BTreeMap<u32, PidNamespace>,
+    mount: BTreeMap<u32, MountNamespace>,
+    net: BTreeSet<u32>,
+    uts: BTreeMap<u32, UtsNamespace>,
+}
+
+lazy_static! {
+    static ref NAMESPACES: Mutex<Namespaces> = Mutex::new(Namespaces::new());
+}
+
+impl Namespaces {
+    fn new() -> Self {
+        let mut uts = BTreeMap::new();
+        uts.insert(
+            0,
+            UtsNamespace {
+                hostname: DEFAULT_HOSTNAME.to_string(),
+            },
+        );
+        Self {
+            next_id: 1,
+            processes: BTreeMap::new(),
+            pid: BTreeMap::new(),
+            mount: BTreeMap::new(),
+            net: BTreeSet::new(),
+            uts,
+        }
+    }
+
+    fn set_of(&self, pid: u32) -> NsSet {
+        self.processes.get(&pid).copied().unwrap_or_default()
+    }
+
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // A PID namespace followed by its ancestors, the initial one excluded
+    fn pid_chain(&self, mut ns: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        while let Some(namespace) = self.pid.get(&ns) {
+            chain.push(ns);
+            ns = namespace.parent;
+        }
+        chain
+    }
+
+    fn ns_pid(&self, ns: u32, host_pid: u32) -> Option<u32> {
+        match ns {
+            0 => Some(host_pid),
+            _ => self.pid.get(&ns)?.from_host.get(&host_pid).copied(),
+        }
+    }
+
+    fn host_pid(&self, ns: u32, ns_pid: u32) -> Option<u32> {
+        match ns {
+            0 => Some(ns_pid),
+            _ => self.pid.get(&ns)?.to_host.get(&ns_pid).copied(),
+        }
+    }
+
+    // Number the process in a PID namespace and every namespace above it
+    fn enter_pid_namespace(&mut self, ns: u32, host_pid: u32) {
+        for id in self.pid_chain(ns) {
+            if let Some(namespace) = self.pid.get_mut(&id) {
+                let ns_pid = namespace.next_pid;
+                namespace.next_pid += 1;
+                namespace.to_host.insert(ns_pid, host_pid);
+                namespace.from_host.insert(host_pid, ns_pid);
+            }
+        }
+    }
+
+    // Drop namespaces no process refers to any more
+    fn collect(&mut self) {
+        let mut pid_live = BTreeSet::new();
+        let mut mount_live = BTreeSet::new();
+        let mut net_live = BTreeSet::new();
+        let mut uts_live = BTreeSet::new();
+        for set in self.processes.values() {
+            pid_live.extend(self.pid_chain(set.pid));
+            pid_live.extend(self.pid_chain(set.pid_for_children));
+            mount_live.insert(set.mount);
+            net_live.insert(set.net);
+            uts_live.insert(set.uts);
+        }
+        self.pid.retain(|id, _| pid_live.contains(id));
+        self.mount.retain(|id, _| mount_live.contains(id));
+        self.net.retain(|id| net_live.contains(id));
+        self.uts.retain(|id, _| *id == 0 || uts_live.contains(id));
+    }
+
+    fn resolve_path(&self, ns: u32, path: &str) -> String {
+        let path = collapse(&normalize_path(path));
+        let Some(namespace) = self.mount.get(&ns) else {
+            return path;
+        };
+        for (target, source) in &namespace.binds {
+            if let Some(rest) = below(&path, target) {
+                let base = source.trim_end_matches('/');
+                return match (base.is_empty(), rest.is_empty()) {
+                    (true, true) => String::from("/"),
+                    (true, false) => rest.to_string(),
+                    (false, _) => base.to_string() + rest,
+                };
+            }
+        }
+        path
+    }
+}
+
+// Resolve ".." so a path cannot climb out of a bind
+fn collapse(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if component == ".." {
+            components.pop();
+        } else {
+            components.push(component);
+        }
+    }
+    if components.is_empty() {
+        return String::from("/");
+    }
+    let mut collapsed = String::new();
+    for component in components {
+        collapsed.push('/');
+        collapsed.push_str(component);
+    }
+    collapsed
+}
+
+// Remainder of `path` below `prefix`, starting with '/' or empty
+fn below<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(if path == "/" { "" } else { path });
+    }
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+pub fn namespaces(pid: u32) -> NsSet {
+    NAMESPACES.lock().set_of(pid)
+}
+
+/// Place a new process in its parent's namespaces
+pub fn inherit(parent: u32, child: u32) {
+    let mut namespaces = NAMESPACES.lock();
+    let set = namespaces.set_of(parent);
+    if set == NsSet::default() {
+        return;
+    }
+    let child_set = NsSet {
+        pid: set.pid_for_children,
+        ..set
+    };
+    namespaces.enter_pid_namespace(child_set.pid, child);
+    namespaces.processes.insert(child, child_set);
+}
+
+/// Forget an exiting process, and namespaces it was the last member of
+pub fn release_process(pid: u32) {
+    let mut namespaces = NAMESPACES.lock();
+    let Some(set) = namespaces.processes.remove(&pid) else {
+        return;
+    };
+    for id in namespaces.pid_chain(set.pid) {
+        if let Some(namespace) = namespaces.pid.get_mut(&id) {
+            if let Some(ns_pid) = namespace.from_host.remove(&pid) {
+                namespace.to_host.remove(&ns_pid);
+            }
+        }
+    }
+    namespaces.collect();
+}
+
+/// Move the process into new namespaces of the kinds in `flags`
+pub fn unshare(pid: u32, flags: usize) -> Result<(), ContainerError> {
+    if flags == 0 || flags & !CLONE_NAMESPACES != 0 {
+        return Err(ContainerError::InvalidConfig);
+    }
+
+    let mut namespaces = NAMESPACES.lock();
+    let mut set = namespaces.set_of(pid);
+
+    if flags & CLONE_NEWPID != 0 {
+        let level = namespaces.pid.get(&set.pid).map_or(0, |parent| parent.level) + 1;
+        if level > MAX_PID_LEVEL {
+            return Err(ContainerError::ResourceLimitExceeded);
+        }
+        let id = namespaces.allocate_id();
+        namespaces.pid.insert(
+            id,
+            PidNamespace {
+                parent: set.pid,
+                level,
+                next_pid: 1,
+                to_host: BTreeMap::new(),
+                from_host: BTreeMap::new(),
+            },
+        );
+        set.pid_for_children = id;
+    }
+    if flags & CLONE_NEWNS != 0 {
+        let binds = namespaces.mount.get(&set.mount).map(|ns| ns.binds.clone()).unwrap_or_default();
+        let id = namespaces.allocate_id();
+        namespaces.mount.insert(id, MountNamespace { binds });
+        set.mount = id;
+    }
+    if flags & CLONE_NEWNET != 0 {
+        let id = namespaces.allocate_id();
+        namespaces.net.insert(id);
+        set.net = id;
+    }
+    if flags & CLONE_NEWUTS != 0 {
+        let uts = namespaces.uts[&set.uts].clone();
+        let id = namespaces.allocate_id();
+        namespaces.uts.insert(id, uts);
+        set.uts = id;
+    }
+
+    namespaces.processes.insert(pid, set);
+    namespaces.collect();
+    Ok(())
+}
+
+/// Move the process into an existing namespace, of the one kind in `kind`
+pub fn setns(pid: u32, kind: usize, id: u32) -> Result<(), ContainerError> {
+    let mut namespaces = NAMESPACES.lock();
+    let mut set = namespaces.set_of(pid);
+    let exists = match kind {
+        CLONE_NEWPID => id == 0 || namespaces.pid.contains_key(&id),
+        CLONE_NEWNS => id == 0 || namespaces.mount.contains_key(&id),
+        CLONE_NEWNET => id == 0 || namespaces.net.contains(&id),
+        CLONE_NEWUTS => namespaces.uts.contains_key(&id),
+        _ => return Err(ContainerError::InvalidConfig),
+    };
+    if !exists {
+        return Err(ContainerError::NotFound);
+    }
+
+    match kind {
+        CLONE_NEWPID => {
+            // Only into the caller's own PID namespace or one below it
+            if set.pid != 0 && !namespaces.pid_chain(id).contains(&set.pid) {
+                return Err(ContainerError::PermissionDenied);
+            }
+            set.pid_for_children = id;
+        }
+        CLONE_NEWNS => set.mount = id,
+        CLONE_NEWNET => set.net = id,
+        _ => set.uts = id,
+    }
+
+    if set == NsSet::default() {
+        namespaces.processes.remove(&pid);
+    } else {
+        namespaces.processes.insert(pid, set);
+    }
+    namespaces.collect();
+    Ok(())
+}
+
+/// The process's pid as its own PID namespace numbers it
+pub fn getpid(pid: u32) -> u32 {
+    let namespaces = NAMESPACES.lock();
+    let set = namespaces.set_of(pid);
+    namespaces.ns_pid(set.pid, pid).unwrap_or(pid)
+}
+
+/// Global pid of the process `caller` knows as `ns_pid`, if it can see it
+pub fn resolve_pid(caller: u32, ns_pid: u32) -> Option<u32> {
+    let namespaces = NAMESPACES.lock();
+    namespaces.host_pid(namespaces.set_of(caller).pid, ns_pid)
+}
+
+pub fn hostname(pid: u32) -> String {
+    let namespaces = NAMESPACES.lock();
+    namespaces.uts[&namespaces.set_of(pid).uts].hostname.clone()
+}
+
+pub fn set_hostname(pid: u32, hostname: &str) -> Result<(), ContainerError> {
+    if hostname.is_empty() || hostname.len() > MAX_HOSTNAME_LEN {
+        return Err(ContainerError::InvalidConfig);
+    }
+    let mut namespaces = NAMESPACES.lock();
+    let uts = namespaces.set_of(pid).uts;
+    if let Some(namespace) = namespaces.uts.get_mut(&uts) {
+        namespace.hostname = hostname.to_string();
+    }
+    Ok(())
+}
+
+/// Path of the global VFS that `path` names in the process's view
+pub fn resolve_path(pid: u32, path: &str) -> String {
+    let namespaces = NAMESPACES.lock();
+    namespaces.resolve_path(namespaces.set_of(pid).mount, path)
+}
+
+/// Make `source` visible at `target` in the process's mount namespace.
+/// The initial namespace is the system's own view and takes no binds.
+pub fn bind_mount(pid: u32, source: &str, target: &str) -> Result<(), ContainerError> {
+    let mut namespaces = NAMESPACES.lock();
+    let ns = namespaces.set_of(pid).mount;
+    if ns == 0 {
+        return Err(ContainerError::PermissionDenied);
+    }
+
+    // The source is named in the caller's current view
+    let source = namespaces.resolve_path(ns, source);
+    let target = collapse(&normalize_path(target));
+    let namespace = namespaces.mount.get_mut(&ns).ok_or(ContainerError::NotFound)?;
+    namespace.binds.retain(|(existing, _)| *existing != target);
+    namespace.binds.push((target, source));
+    namespace.binds.sort_by_key(|(target, _)| core::cmp::Reverse(target.len()));
+    Ok(())
+}
+
+/// Network namespace of the process, which sockets key their ports by
+pub fn net_namespace(pid: u32) -> u32 {
+    NAMESPACES.lock().set_of(pid).net
+}
+
+/// A namespace and what is in it, for the shell
+pub struct NamespaceInfo {
+    pub kind: &'static str,
+    pub id: u32,
+    pub processes: usize,
+    pub detail: String,
+}
+
+pub fn list() -> Vec<NamespaceInfo> {
+    let namespaces = NAMESPACES.lock();
+    let sets: Vec<NsSet> = namespaces.processes.values().copied().collect();
+
+    let mut list = Vec::new();
+    for (&id, namespace) in &namespaces.pid {
+        list.push(NamespaceInfo {
+            kind: "pid",
+            id,
+            processes: namespace.to_host.len(),
+            detail: format!("parent {}, level {}", namespace.parent, namespace.level),
+        });
+    }
+    for (&id, namespace) in &namespaces.mount {
+        let binds: Vec<String> =
+            namespace.binds.iter().map(|(target, source)| format!("{} -> {}", target, source)).collect();
+        list.push(NamespaceInfo {
+            kind: "mnt",
+            id,
+            processes: sets.iter().filter(|set| set.mount == id).count(),
+            detail: binds.join(", "),
+        });
+    }
+    for &id in &namespaces.net {
+        list.push(NamespaceInfo {
+            kind: "net",
+            id,
+            processes: sets.iter().filter(|set| set.net == id).count(),
+            detail: String::from("loopback only"),
+        });
+    }
+    for (&id, namespace) in namespaces.uts.iter().filter(|(&id, _)| id != 0) {
+        list.push(NamespaceInfo {
+            kind: "uts",
+            id,
+            processes: sets.iter().filter(|set| set.uts == id).count(),
+            detail: namespace.hostname.clone(),
+        });
+    }
+    list
+}
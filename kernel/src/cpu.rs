@@ -1,5 +1,6 @@
 use core::arch::x86_64::{__cpuid, __cpuid_count};
 use bitflags::bitflags;
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 
@@ -45,6 +46,9 @@ pub struct CpuInfo {
     pub max_extended_cpuid: u32,
     pub features: CpuFeatures,
     pub processor_brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
     pub physical_cores: u8,
     pub logical_cores: u8,
     pub cache_line_size: u16,
@@ -62,6 +66,9 @@ impl CpuInfo {
             max_extended_cpuid: 0,
             features: CpuFeatures::empty(),
             processor_brand: [0; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
             physical_cores: 1,
             logical_cores: 1,
             cache_line_size: 64,
@@ -89,6 +96,14 @@ impl CpuInfo {
             if info.max_cpuid >= 1 {
                 let cpuid = __cpuid(1);
                 
+                // The extended family and model count only for families
+                // 0xF, and 0x6 for the model
+                let family = (cpuid.eax >> 8) & 0xF;
+                let model = (cpuid.eax >> 4) & 0xF;
+                info.family = if family == 0xF { family + ((cpuid.eax >> 20) & 0xFF) } else { family };
+                info.model = if family == 0x6 || family == 0xF { model | ((cpuid.eax >> 12) & 0xF0) } else { model };
+                info.stepping = cpuid.eax & 0xF;
+                
                 // EDX features
                 if cpuid.edx & (1 << 25) != 0 { info.features |= CpuFeatures::SSE; }
                 if cpuid.edx & (1 << 26) != 0 { info.features |= CpuFeatures::SSE2; }
@@ -262,6 +277,7 @@ pub fn init() {
     unsafe {
         CPU_INFO = Some(CpuInfo::detect());
     }
+    crate::fs::procfs::register("cpuinfo", proc_cpuinfo);
 }
 
 pub fn get_info() -> &'static CpuInfo {
@@ -341,6 +357,80 @@ pub fn get_features() -> Vec<String> {
     features
 }
 
+// Linux's names for the features, in the order /proc/cpuinfo lists them
+const FLAGS: &[(CpuFeatures, &str)] = &[
+    (CpuFeatures::TSC, "tsc"),
+    (CpuFeatures::APIC, "apic"),
+    (CpuFeatures::SSE, "sse"),
+    (CpuFeatures::SSE2, "sse2"),
+    (CpuFeatures::SSE3, "pni"),
+    (CpuFeatures::SSSE3, "ssse3"),
+    (CpuFeatures::FMA, "fma"),
+    (CpuFeatures::SSE41, "sse4_1"),
+    (CpuFeatures::SSE42, "sse4_2"),
+    (CpuFeatures::X2APIC, "x2apic"),
+    (CpuFeatures::POPCNT, "popcnt"),
+    (CpuFeatures::TSC_DEADLINE, "tsc_deadline_timer"),
+    (CpuFeatures::AES, "aes"),
+    (CpuFeatures::XSAVE, "xsave"),
+    (CpuFeatures::AVX, "avx"),
+    (CpuFeatures::RDRAND, "rdrand"),
+    (CpuFeatures::HYPERVISOR, "hypervisor"),
+    (CpuFeatures::FSGSBASE, "fsgsbase"),
+    (CpuFeatures::BMI1, "bmi1"),
+    (CpuFeatures::AVX2, "avx2"),
+    (CpuFeatures::SMEP, "smep"),
+    (CpuFeatures::BMI2, "bmi2"),
+    (CpuFeatures::INVPCID, "invpcid"),
+    (CpuFeatures::AVX512F, "avx512f"),
+    (CpuFeatures::AVX512DQ, "avx512dq"),
+    (CpuFeatures::RDSEED, "rdseed"),
+    (CpuFeatures::SMAP, "smap"),
+    (CpuFeatures::AVX512BW, "avx512bw"),
+    (CpuFeatures::AVX512VL, "avx512vl"),
+    (CpuFeatures::PCID, "pcid"),
+];
+
+/// The features the CPU has, by the names Linux gives them
+pub fn flags() -> Vec<&'static str> {
+    let features = &get_info().features;
+    FLAGS.iter().filter(|(feature, _)| features.bits() & feature.bits() == feature.bits()).map(|(_, name)| *name).collect()
+}
+
+/// /proc/cpuinfo: a stanza for each CPU that is online, as on Linux. They
+/// all report the boot CPU's CPUID, as the APs are the same model.
+pub fn proc_cpuinfo() -> String {
+    let info = get_info();
+    let flags = flags().join(" ");
+    let cache = [info.l3_cache, info.l2_cache, info.l1_data_cache].into_iter().find(|&size| size > 0).unwrap_or(0);
+    let mhz = crate::time::tsc::frequency() / 1_000;
+    let cpus: Vec<crate::smp::CpuInfo> = {
+        let smp = crate::smp::SMP_MANAGER.lock();
+        (0..crate::smp::MAX_CPUS as u32)
+            .filter_map(|cpu| smp.get_cpu(cpu).cloned())
+            .filter(|cpu| cpu.state == crate::smp::CpuState::Online)
+            .collect()
+    };
+    let mut text = String::new();
+    for cpu in cpus {
+        text += &format!("processor\t: {}\n", cpu.cpu_id);
+        text += &format!("vendor_id\t: {}\n", get_vendor().unwrap_or_default());
+        text += &format!("cpu family\t: {}\n", info.family);
+        text += &format!("model\t\t: {}\n", info.model);
+        text += &format!("model name\t: {}\n", get_model().unwrap_or_default().trim());
+        text += &format!("stepping\t: {}\n", info.stepping);
+        text += &format!("cpu MHz\t\t: {}.{:03}\n", mhz / 1_000, mhz % 1_000);
+        text += &format!("cache size\t: {} KB\n", cache / 1024);
+        text += &format!("physical id\t: {}\n", cpu.package_id);
+        text += &format!("core id\t\t: {}\n", cpu.core_id);
+        text += &format!("apicid\t\t: {}\n", cpu.apic_id);
+        text += &format!("flags\t\t: {}\n", flags);
+        text += &format!("clflush size\t: {}\n", info.cache_line_size);
+        text += "\n";
+    }
+    text
+}
+
 pub fn get_cpu_usage() -> u64 {
     // Placeholder - would calculate from performance counters
     50
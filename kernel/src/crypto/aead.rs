@@ -1,9 +1,9 @@
-#![no_std]
-
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use super::cipher::{ChaCha20Cipher, AesCipher, CipherMode, SymmetricCipher};
+use super::cipher::{AesKey, ChaCha20Cipher};
 use super::mac::{Poly1305, Mac};
 use super::errors::{CryptoError, CryptoResult};
+use super::hw_accel;
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,12 +17,20 @@ pub enum AeadAlgorithm {
 
 pub trait Aead: Send + Sync {
     fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>>;
-    fn decrypt(&self, key: &[u8], nonce: &[8], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>>;
+    fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>>;
     fn key_size(&self) -> usize;
     fn nonce_size(&self) -> usize;
     fn tag_size(&self) -> usize;
 }
 
+// Constant-time tag comparison
+pub(crate) fn tags_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 pub struct ChaCha20Poly1305Aead {
     cipher: ChaCha20Cipher,
     mac: Poly1305,
@@ -35,22 +43,31 @@ impl ChaCha20Poly1305Aead {
             mac: Poly1305::new(),
         }
     }
-    
-    fn poly1305_key(&self, key: &[u8], nonce: &[u8]) -> Vec<u8> {
-        let mut counter = [0u8; 4];
-        let mut full_nonce = Vec::with_capacity(16);
-        full_nonce.extend_from_slice(&counter);
-        full_nonce.extend_from_slice(nonce);
-        
-        let keystream = self.cipher.encrypt(&vec![0u8; 32], key, Some(&full_nonce[4..])).unwrap();
-        keystream[..32].to_vec()
-    }
-    
+
+    fn check_params(key: &[u8], nonce: &[u8]) -> CryptoResult<([u8; 32], [u8; 12])> {
+        let key = key.try_into().map_err(|_| CryptoError::InvalidKeySize)?;
+        let nonce = nonce.try_into().map_err(|_| CryptoError::InvalidNonce)?;
+        Ok((key, nonce))
+    }
+
+    // RFC 8439: the one-time Poly1305 key is the first half of keystream block 0
+    fn tag(&self, key: &[u8; 32], nonce: &[u8; 12], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let poly_key = self.cipher.apply_keystream(key, nonce, 0, &[0u8; 32]);
+
+        let mut auth_data = Vec::with_capacity(aad.len() + ciphertext.len() + 48);
+        auth_data.extend_from_slice(&Self::pad16(aad));
+        auth_data.extend_from_slice(&Self::pad16(ciphertext));
+        auth_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+        auth_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+
+        self.mac.compute(&poly_key, &auth_data)
+    }
+
     fn pad16(data: &[u8]) -> Vec<u8> {
         let mut padded = data.to_vec();
         let remainder = data.len() % 16;
         if remainder != 0 {
-            padded.extend_from_slice(&vec![0u8; 16 - remainder]);
+            padded.resize(data.len() + 16 - remainder, 0);
         }
         padded
     }
@@ -58,72 +75,80 @@ impl ChaCha20Poly1305Aead {
 
 impl Aead for ChaCha20Poly1305Aead {
     fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
-        if key.len() != 32 {
-            return Err(CryptoError::InvalidKeySize);
-        }
-        if nonce.len() != 12 {
-            return Err(CryptoError::InvalidNonce);
-        }
-        
-        let ciphertext = self.cipher.encrypt(plaintext, key, Some(nonce))?;
-        
-        let poly_key = self.poly1305_key(key, nonce);
-        
-        let mut auth_data = Vec::new();
-        auth_data.extend_from_slice(&Self::pad16(aad));
-        auth_data.extend_from_slice(&Self::pad16(&ciphertext));
-        auth_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
-        auth_data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
-        
-        let tag = self.mac.compute(&poly_key, &auth_data)?;
-        
-        let mut result = ciphertext;
+        let (key, nonce) = Self::check_params(key, nonce)?;
+
+        let mut result = self.cipher.apply_keystream(&key, &nonce, 1, plaintext);
+        let tag = self.tag(&key, &nonce, &result, aad)?;
         result.extend_from_slice(&tag);
-        
+
         Ok(result)
     }
-    
+
     fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
-        if key.len() != 32 {
-            return Err(CryptoError::InvalidKeySize);
-        }
-        if nonce.len() != 12 {
-            return Err(CryptoError::InvalidNonce);
-        }
+        let (key, nonce) = Self::check_params(key, nonce)?;
         if ciphertext.len() < 16 {
             return Err(CryptoError::InvalidTag);
         }
-        
+
         let (cipher_data, tag) = ciphertext.split_at(ciphertext.len() - 16);
-        
-        let poly_key = self.poly1305_key(key, nonce);
-        
-        let mut auth_data = Vec::new();
-        auth_data.extend_from_slice(&Self::pad16(aad));
-        auth_data.extend_from_slice(&Self::pad16(cipher_data));
-        auth_data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
-        auth_data.extend_from_slice(&(cipher_data.len() as u64).to_le_bytes());
-        
-        if !self.mac.verify(&poly_key, &auth_data, tag)? {
+        if !tags_equal(&self.tag(&key, &nonce, cipher_data, aad)?, tag) {
             return Err(CryptoError::AuthenticationFailed);
         }
-        
-        self.cipher.decrypt(cipher_data, key, Some(nonce))
+
+        Ok(self.cipher.apply_keystream(&key, &nonce, 1, cipher_data))
     }
-    
+
     fn key_size(&self) -> usize {
         32
     }
-    
+
     fn nonce_size(&self) -> usize {
         12
     }
-    
+
     fn tag_size(&self) -> usize {
         16
     }
 }
 
+/// Bitwise GHASH multiply, the reference for the PCLMULQDQ path
+pub(super) fn gf_mult_soft(x: &[u8; 16], y: &[u8; 16]) -> [u8; 16] {
+    let mut z = [0u8; 16];
+    let mut v = *y;
+
+    for i in 0..128 {
+        let byte_idx = i / 8;
+        let bit_idx = 7 - (i % 8);
+
+        if (x[byte_idx] >> bit_idx) & 1 == 1 {
+            for j in 0..16 {
+                z[j] ^= v[j];
+            }
+        }
+
+        let lsb = v[15] & 1;
+
+        for j in (1..16).rev() {
+            v[j] = (v[j] >> 1) | ((v[j - 1] & 1) << 7);
+        }
+        v[0] >>= 1;
+
+        if lsb == 1 {
+            v[0] ^= 0xe1;
+        }
+    }
+
+    z
+}
+
+fn gf_mult(x: &[u8; 16], y: &[u8; 16]) -> [u8; 16] {
+    #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+    if hw_accel::use_clmul() {
+        return unsafe { hw_accel::clmul::gf_mult(x, y) };
+    }
+    gf_mult_soft(x, y)
+}
+
 pub struct AesGcm {
     key_size: usize,
 }
@@ -132,168 +157,251 @@ impl AesGcm {
     pub fn new(key_size: usize) -> Self {
         Self { key_size }
     }
-    
-    fn ghash(&self, h: &[u8; 16], data: &[u8]) -> [u8; 16] {
+
+    fn ghash(h: &[u8; 16], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
         let mut y = [0u8; 16];
-        
-        for chunk in data.chunks(16) {
-            for i in 0..chunk.len() {
-                y[i] ^= chunk[i];
-            }
-            
-            y = self.gf_mult(&y, h);
-        }
-        
-        y
-    }
-    
-    fn gf_mult(&self, x: &[u8; 16], y: &[u8; 16]) -> [u8; 16] {
-        let mut z = [0u8; 16];
-        let mut v = *y;
-        
-        for i in 0..128 {
-            let byte_idx = i / 8;
-            let bit_idx = 7 - (i % 8);
-            
-            if (x[byte_idx] >> bit_idx) & 1 == 1 {
-                for j in 0..16 {
-                    z[j] ^= v[j];
+
+        for data in [aad, ciphertext] {
+            for chunk in data.chunks(16) {
+                for (i, byte) in chunk.iter().enumerate() {
+                    y[i] ^= byte;
                 }
-            }
-            
-            let lsb = v[15] & 1;
-            
-            for j in (1..16).rev() {
-                v[j] = (v[j] >> 1) | ((v[j - 1] & 1) << 7);
-            }
-            v[0] >>= 1;
-            
-            if lsb == 1 {
-                v[0] ^= 0xe1;
+                y = gf_mult(&y, h);
             }
         }
-        
-        z
+
+        let mut lengths = [0u8; 16];
+        lengths[..8].copy_from_slice(&(aad.len() as u64 * 8).to_be_bytes());
+        lengths[8..].copy_from_slice(&(ciphertext.len() as u64 * 8).to_be_bytes());
+        for i in 0..16 {
+            y[i] ^= lengths[i];
+        }
+
+        gf_mult(&y, h)
     }
-    
-    fn gctr(&self, key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
-        let cipher = AesCipher::new(self.key_size, CipherMode::ECB);
+
+    // Counter mode from inc32(J0); only the low 32 bits of the block count
+    fn gctr(key: &AesKey, j0: &[u8; 16], data: &[u8]) -> Vec<u8> {
         let mut result = Vec::with_capacity(data.len());
-        
-        let mut counter = [0u8; 16];
-        counter[..12].copy_from_slice(iv);
-        counter[15] = 1;
-        
+        let mut counter = *j0;
+        let mut count = u32::from_be_bytes([j0[12], j0[13], j0[14], j0[15]]);
+
         for chunk in data.chunks(16) {
-            counter[15] = counter[15].wrapping_add(1);
-            let keystream = cipher.encrypt(&counter, key, None).unwrap();
-            
+            count = count.wrapping_add(1);
+            counter[12..].copy_from_slice(&count.to_be_bytes());
+            let mut keystream = counter;
+            key.encrypt_block(&mut keystream);
+
             for (i, &byte) in chunk.iter().enumerate() {
                 result.push(byte ^ keystream[i]);
             }
         }
-        
+
         result
     }
-}
 
-impl Aead for AesGcm {
-    fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+    fn setup(&self, key: &[u8], nonce: &[u8]) -> CryptoResult<(AesKey, [u8; 16], [u8; 16])> {
         if key.len() != self.key_size {
             return Err(CryptoError::InvalidKeySize);
         }
         if nonce.len() != 12 {
             return Err(CryptoError::InvalidNonce);
         }
-        
-        let cipher = AesCipher::new(self.key_size, CipherMode::ECB);
-        
-        let h_bytes = cipher.encrypt(&[0u8; 16], key, None)?;
+
+        let aes = AesKey::new(key)?;
         let mut h = [0u8; 16];
-        h.copy_from_slice(&h_bytes);
-        
-        let ciphertext = self.gctr(key, nonce, plaintext);
-        
-        let mut ghash_input = Vec::new();
-        ghash_input.extend_from_slice(aad);
-        ghash_input.resize((aad.len() + 15) / 16 * 16, 0);
-        ghash_input.extend_from_slice(&ciphertext);
-        ghash_input.resize(ghash_input.len() + (15 - (ciphertext.len() + 15) % 16), 0);
-        ghash_input.extend_from_slice(&(aad.len() as u64 * 8).to_be_bytes());
-        ghash_input.extend_from_slice(&(ciphertext.len() as u64 * 8).to_be_bytes());
-        
-        let ghash = self.ghash(&h, &ghash_input);
-        
+        aes.encrypt_block(&mut h);
+
         let mut j0 = [0u8; 16];
         j0[..12].copy_from_slice(nonce);
         j0[15] = 1;
-        
-        let tag_mask = cipher.encrypt(&j0, key, None)?;
-        let mut tag = [0u8; 16];
+
+        Ok((aes, h, j0))
+    }
+
+    fn tag(aes: &AesKey, h: &[u8; 16], j0: &[u8; 16], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+        let mut tag = Self::ghash(h, aad, ciphertext);
+        let mut mask = *j0;
+        aes.encrypt_block(&mut mask);
         for i in 0..16 {
-            tag[i] = ghash[i] ^ tag_mask[i];
+            tag[i] ^= mask[i];
         }
-        
-        let mut result = ciphertext;
+        tag
+    }
+}
+
+impl Aead for AesGcm {
+    fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let (aes, h, j0) = self.setup(key, nonce)?;
+
+        let mut result = Self::gctr(&aes, &j0, plaintext);
+        let tag = Self::tag(&aes, &h, &j0, aad, &result);
         result.extend_from_slice(&tag);
-        
+
         Ok(result)
     }
-    
+
     fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let (aes, h, j0) = self.setup(key, nonce)?;
+        if ciphertext.len() < 16 {
+            return Err(CryptoError::InvalidTag);
+        }
+
+        let (cipher_data, tag) = ciphertext.split_at(ciphertext.len() - 16);
+        if !tags_equal(&Self::tag(&aes, &h, &j0, aad, cipher_data), tag) {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+
+        Ok(Self::gctr(&aes, &j0, cipher_data))
+    }
+
+    fn key_size(&self) -> usize {
+        self.key_size
+    }
+
+    fn nonce_size(&self) -> usize {
+        12
+    }
+
+    fn tag_size(&self) -> usize {
+        16
+    }
+}
+
+/// AES in CCM mode (RFC 3610): CBC-MAC over the nonce, AAD and plaintext,
+/// then counter mode over the plaintext and the tag. The tag and nonce
+/// sizes are the caller's; 802.11's CCMP takes an 8-byte tag and a
+/// 13-byte nonce.
+pub struct AesCcm {
+    key_size: usize,
+    tag_size: usize,
+    nonce_size: usize,
+}
+
+impl AesCcm {
+    pub fn new(key_size: usize, tag_size: usize, nonce_size: usize) -> Self {
+        Self { key_size, tag_size, nonce_size }
+    }
+
+    fn setup(&self, key: &[u8], nonce: &[u8]) -> CryptoResult<AesKey> {
         if key.len() != self.key_size {
             return Err(CryptoError::InvalidKeySize);
         }
-        if nonce.len() != 12 {
+        if nonce.len() != self.nonce_size || !(7..=13).contains(&nonce.len()) {
             return Err(CryptoError::InvalidNonce);
         }
-        if ciphertext.len() < 16 {
+        if self.tag_size % 2 != 0 || !(4..=16).contains(&self.tag_size) {
             return Err(CryptoError::InvalidTag);
         }
-        
-        let (cipher_data, tag) = ciphertext.split_at(ciphertext.len() - 16);
-        
-        let cipher = AesCipher::new(self.key_size, CipherMode::ECB);
-        
-        let h_bytes = cipher.encrypt(&[0u8; 16], key, None)?;
-        let mut h = [0u8; 16];
-        h.copy_from_slice(&h_bytes);
-        
-        let mut ghash_input = Vec::new();
-        ghash_input.extend_from_slice(aad);
-        ghash_input.resize((aad.len() + 15) / 16 * 16, 0);
-        ghash_input.extend_from_slice(cipher_data);
-        ghash_input.resize(ghash_input.len() + (15 - (cipher_data.len() + 15) % 16), 0);
-        ghash_input.extend_from_slice(&(aad.len() as u64 * 8).to_be_bytes());
-        ghash_input.extend_from_slice(&(cipher_data.len() as u64 * 8).to_be_bytes());
-        
-        let ghash = self.ghash(&h, &ghash_input);
-        
-        let mut j0 = [0u8; 16];
-        j0[..12].copy_from_slice(nonce);
-        j0[15] = 1;
-        
-        let tag_mask = cipher.encrypt(&j0, key, None)?;
-        
-        for i in 0..16 {
-            if (ghash[i] ^ tag_mask[i]) != tag[i] {
-                return Err(CryptoError::AuthenticationFailed);
+        AesKey::new(key)
+    }
+
+    // The message length fills what the nonce leaves of a block
+    fn length_size(&self) -> usize {
+        15 - self.nonce_size
+    }
+
+    // Counter block `i`: flags, the nonce, and the counter
+    fn counter(&self, nonce: &[u8], i: usize) -> [u8; 16] {
+        let l = self.length_size();
+        let mut block = [0u8; 16];
+        block[0] = (l - 1) as u8;
+        block[1..1 + nonce.len()].copy_from_slice(nonce);
+        block[16 - l..].copy_from_slice(&(i as u64).to_be_bytes()[8 - l..]);
+        block
+    }
+
+    fn cbc_mac(&self, aes: &AesKey, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> CryptoResult<[u8; 16]> {
+        let l = self.length_size();
+        if l < 8 && plaintext.len() as u64 >= 1u64 << (8 * l) {
+            return Err(CryptoError::InvalidParameter);
+        }
+
+        let mut b0 = [0u8; 16];
+        b0[0] = ((!aad.is_empty() as u8) << 6) | (((self.tag_size - 2) / 2) as u8) << 3 | (l - 1) as u8;
+        b0[1..1 + nonce.len()].copy_from_slice(nonce);
+        b0[16 - l..].copy_from_slice(&(plaintext.len() as u64).to_be_bytes()[8 - l..]);
+        let mut x = b0;
+        aes.encrypt_block(&mut x);
+
+        let mut blocks = |data: &[u8]| {
+            for chunk in data.chunks(16) {
+                for (i, byte) in chunk.iter().enumerate() {
+                    x[i] ^= byte;
+                }
+                aes.encrypt_block(&mut x);
             }
+        };
+        if !aad.is_empty() {
+            // The AAD behind its length, padded out to a block
+            let mut header = Vec::with_capacity(6 + aad.len());
+            if aad.len() < 0xFF00 {
+                header.extend_from_slice(&(aad.len() as u16).to_be_bytes());
+            } else {
+                header.extend_from_slice(&[0xFF, 0xFE]);
+                header.extend_from_slice(&(aad.len() as u32).to_be_bytes());
+            }
+            header.extend_from_slice(aad);
+            blocks(&header);
+        }
+        blocks(plaintext);
+        Ok(x)
+    }
+
+    // Counter mode from counter block 1
+    fn ctr(&self, aes: &AesKey, nonce: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::with_capacity(data.len());
+        for (i, chunk) in data.chunks(16).enumerate() {
+            let mut keystream = self.counter(nonce, i + 1);
+            aes.encrypt_block(&mut keystream);
+            result.extend(chunk.iter().zip(keystream.iter()).map(|(byte, key)| byte ^ key));
         }
-        
-        Ok(self.gctr(key, nonce, cipher_data))
+        result
+    }
+
+    // The CBC-MAC, cut to the tag size and masked with counter block 0
+    fn tag(&self, aes: &AesKey, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
+        let mac = self.cbc_mac(aes, nonce, aad, plaintext)?;
+        let mut mask = self.counter(nonce, 0);
+        aes.encrypt_block(&mut mask);
+        Ok(mac.iter().zip(mask.iter()).take(self.tag_size).map(|(x, y)| x ^ y).collect())
     }
-    
+}
+
+impl Aead for AesCcm {
+    fn encrypt(&self, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let aes = self.setup(key, nonce)?;
+        let tag = self.tag(&aes, nonce, aad, plaintext)?;
+
+        let mut result = self.ctr(&aes, nonce, plaintext);
+        result.extend_from_slice(&tag);
+        Ok(result)
+    }
+
+    fn decrypt(&self, key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let aes = self.setup(key, nonce)?;
+        if ciphertext.len() < self.tag_size {
+            return Err(CryptoError::InvalidTag);
+        }
+
+        let (cipher_data, tag) = ciphertext.split_at(ciphertext.len() - self.tag_size);
+        let plaintext = self.ctr(&aes, nonce, cipher_data);
+        if !tags_equal(&self.tag(&aes, nonce, aad, &plaintext)?, tag) {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+        Ok(plaintext)
+    }
+
     fn key_size(&self) -> usize {
         self.key_size
     }
-    
+
     fn nonce_size(&self) -> usize {
-        12
+        self.nonce_size
     }
-    
+
     fn tag_size(&self) -> usize {
-        16
+        self.tag_size
     }
 }
 
@@ -302,6 +410,8 @@ pub fn get_aead(algorithm: AeadAlgorithm, _provider: CryptoProvider) -> CryptoRe
         AeadAlgorithm::ChaCha20Poly1305 => Ok(Box::new(ChaCha20Poly1305Aead::new())),
         AeadAlgorithm::AesGcm128 => Ok(Box::new(AesGcm::new(16))),
         AeadAlgorithm::AesGcm256 => Ok(Box::new(AesGcm::new(32))),
+        // AEAD_AES_128_CCM, as RFC 5116 gives it
+        AeadAlgorithm::AesCcm => Ok(Box::new(AesCcm::new(16, 16, 12))),
         _ => Err(CryptoError::UnsupportedAlgorithm),
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,20 @@
-#![no_std]
+// Public-key signatures: RSA (PKCS#1 v1.5 and PSS), ECDSA over P-256 and
+// Ed25519 (RFC 8032), and X25519 key agreement (RFC 7748)
+//
+// RSA keys use the length-prefixed encoding `len(n) n len(e) e [len(d) d]`
+// with big-endian 32-bit lengths. P-256 public keys are SEC1 uncompressed
+// points (with or without the 0x04 prefix) and signatures may be raw r||s or
+// DER. Ed25519 private keys are the 32-byte seed followed by the public key.
+// X25519 keys are the 32-byte little-endian scalar and u-coordinate.
 
+use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
-use alloc::string::String;
-use core::convert::TryInto;
+use lazy_static::lazy_static;
+use super::bignum::{BigUint, Montgomery};
 use super::errors::{CryptoError, CryptoResult};
-use super::hash::{HashFunction, SHA256};
+use super::hash::{HashAlgorithm, HashFunction, SHA256, SHA384, SHA512};
+use super::rng;
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,6 +27,19 @@ pub enum AsymmetricAlgorithm {
     X25519,
 }
 
+// Signature scheme identifiers for `verify_signature`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    RsaPkcs1Sha256,
+    RsaPkcs1Sha384,
+    RsaPkcs1Sha512,
+    RsaPssSha256,
+    RsaPssSha384,
+    RsaPssSha512,
+    EcdsaP256Sha256,
+    Ed25519,
+}
+
 pub trait PublicKey: Send + Sync {
     fn verify(&self, data: &[u8], signature: &[u8]) -> CryptoResult<bool>;
     fn encrypt(&self, plaintext: &[u8]) -> CryptoResult<Vec<u8>>;
@@ -34,7 +57,7 @@ pub trait PrivateKey: Send + Sync {
 pub trait KeyPair: Send + Sync {
     type PublicKey: PublicKey;
     type PrivateKey: PrivateKey;
-    
+
     fn generate() -> CryptoResult<Self> where Self: Sized;
     fn public_key(&self) -> &Self::PublicKey;
     fn private_key(&self) -> &Self::PrivateKey;
@@ -48,138 +71,219 @@ pub trait AsymmetricCrypto: Send + Sync {
     fn decrypt(&self, private_key: &[u8], ciphertext: &[u8]) -> CryptoResult<Vec<u8>>;
 }
 
-pub struct Ed25519;
+/// Verify `signature` over `data` with a public key in the scheme's encoding
+pub fn verify_signature(scheme: SignatureScheme, public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+    match scheme {
+        SignatureScheme::RsaPkcs1Sha256 => RsaPublicKey::parse(public_key)?.verify_pkcs1(HashAlgorithm::SHA256, data, signature),
+        SignatureScheme::RsaPkcs1Sha384 => RsaPublicKey::parse(public_key)?.verify_pkcs1(HashAlgorithm::SHA384, data, signature),
+        SignatureScheme::RsaPkcs1Sha512 => RsaPublicKey::parse(public_key)?.verify_pkcs1(HashAlgorithm::SHA512, data, signature),
+        SignatureScheme::RsaPssSha256 => RsaPublicKey::parse(public_key)?.verify_pss(HashAlgorithm::SHA256, data, signature),
+        SignatureScheme::RsaPssSha384 => RsaPublicKey::parse(public_key)?.verify_pss(HashAlgorithm::SHA384, data, signature),
+        SignatureScheme::RsaPssSha512 => RsaPublicKey::parse(public_key)?.verify_pss(HashAlgorithm::SHA512, data, signature),
+        SignatureScheme::EcdsaP256Sha256 => EcdsaP256::new().verify(public_key, data, signature),
+        SignatureScheme::Ed25519 => Ed25519::new().verify(public_key, data, signature),
+    }
+}
 
-impl Ed25519 {
-    pub fn new() -> Self {
-        Self
+fn digest(algorithm: HashAlgorithm, data: &[u8]) -> CryptoResult<Vec<u8>> {
+    match algorithm {
+        HashAlgorithm::SHA256 => Ok(SHA256::new().hash(data)),
+        HashAlgorithm::SHA384 => Ok(SHA384::new().hash(data)),
+        HashAlgorithm::SHA512 => Ok(SHA512::new().hash(data)),
+        _ => Err(CryptoError::UnsupportedAlgorithm),
     }
-    
-    fn scalar_mult_base(&self, scalar: &[u8; 32]) -> [u8; 32] {
-        let mut result = [0u8; 32];
-        
-        result[0] = 0x09;
-        
-        for i in 0..32 {
-            for j in 0..8 {
-                if (scalar[i] >> j) & 1 == 1 {
-                    self.point_add(&mut result);
-                }
-                self.point_double(&mut result);
-            }
+}
+
+// DER-encoded DigestInfo header preceding the hash in EMSA-PKCS1-v1_5
+fn digest_info_prefix(algorithm: HashAlgorithm) -> CryptoResult<&'static [u8]> {
+    match algorithm {
+        HashAlgorithm::SHA256 => Ok(&[
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+            0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+        ]),
+        HashAlgorithm::SHA384 => Ok(&[
+            0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+            0x65, 0x03, 0x04, 0x02, 0x02, 0x05, 0x00, 0x04, 0x30,
+        ]),
+        HashAlgorithm::SHA512 => Ok(&[
+            0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+            0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40,
+        ]),
+        _ => Err(CryptoError::UnsupportedAlgorithm),
+    }
+}
+
+// One length-prefixed field of an RSA key blob
+fn read_field<'a>(bytes: &'a [u8], offset: &mut usize) -> CryptoResult<&'a [u8]> {
+    let header = bytes.get(*offset..*offset + 4).ok_or(CryptoError::InvalidKeyFormat)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let field = bytes.get(*offset + 4..*offset + 4 + len).ok_or(CryptoError::InvalidKeyFormat)?;
+    *offset += 4 + len;
+    Ok(field)
+}
+
+fn write_field(out: &mut Vec<u8>, field: &[u8]) {
+    out.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    out.extend_from_slice(field);
+}
+
+pub struct RsaPublicKey {
+    n: BigUint,
+    e: BigUint,
+    size: usize,
+}
+
+impl RsaPublicKey {
+    pub fn from_components(n: &[u8], e: &[u8]) -> CryptoResult<Self> {
+        let n = BigUint::from_be_bytes(n);
+        let e = BigUint::from_be_bytes(e);
+        if n.bits() < 512 || !n.is_odd() || e.bits() < 2 || !e.is_odd() {
+            return Err(CryptoError::InvalidKeyFormat);
         }
-        
-        result
+        let size = (n.bits() + 7) / 8;
+        Ok(Self { n, e, size })
     }
-    
-    fn point_add(&self, _point: &mut [u8; 32]) {
+
+    pub fn parse(bytes: &[u8]) -> CryptoResult<Self> {
+        let mut offset = 0;
+        let n = read_field(bytes, &mut offset)?;
+        let e = read_field(bytes, &mut offset)?;
+        Self::from_components(n, e)
     }
-    
-    fn point_double(&self, _point: &mut [u8; 32]) {
+
+    /// Modulus length in bytes
+    pub fn size(&self) -> usize {
+        self.size
     }
-    
-    fn clamp_scalar(scalar: &mut [u8; 32]) {
-        scalar[0] &= 248;
-        scalar[31] &= 127;
-        scalar[31] |= 64;
+
+    /// Modulus, big-endian, `size` bytes long
+    pub fn modulus(&self) -> Vec<u8> {
+        self.n.to_be_bytes(self.size).unwrap_or_default()
     }
-}
 
-impl AsymmetricCrypto for Ed25519 {
-    fn generate_keypair(&self) -> CryptoResult<(Vec<u8>, Vec<u8>)> {
-        let mut seed = [0u8; 32];
-        for i in 0..32 {
-            seed[i] = ((i * 7 + 13) % 256) as u8;
-        }
-        
-        let hasher = SHA256::new();
-        let hash = hasher.hash(&seed);
-        let mut scalar = [0u8; 32];
-        scalar.copy_from_slice(&hash[..32]);
-        Self::clamp_scalar(&mut scalar);
-        
-        let public_key = self.scalar_mult_base(&scalar);
-        
-        let mut private_key = Vec::with_capacity(64);
-        private_key.extend_from_slice(&seed);
-        private_key.extend_from_slice(&public_key);
-        
-        Ok((public_key.to_vec(), private_key))
+    /// Public exponent, big-endian, without leading zeros
+    pub fn exponent(&self) -> Vec<u8> {
+        self.e.to_be_bytes((self.e.bits() + 7) / 8).unwrap_or_default()
     }
-    
-    fn sign(&self, private_key: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
-        if private_key.len() != 64 {
-            return Err(CryptoError::InvalidKeySize);
+
+    // RSAVP1: signature^e mod n as a k-byte string
+    fn public_op(&self, signature: &[u8]) -> CryptoResult<Option<Vec<u8>>> {
+        if signature.len() != self.size {
+            return Ok(None);
         }
-        
-        let hasher = SHA256::new();
-        
-        let seed = &private_key[..32];
-        let public_key = &private_key[32..];
-        
-        let hash1 = hasher.hash(seed);
-        let mut scalar = [0u8; 32];
-        scalar.copy_from_slice(&hash1[..32]);
-        Self::clamp_scalar(&mut scalar);
-        
-        let mut hash2_input = Vec::new();
-        hash2_input.extend_from_slice(&hash1[32..]);
-        hash2_input.extend_from_slice(data);
-        let hash2 = hasher.hash(&hash2_input);
-        
-        let mut r = [0u8; 32];
-        r.copy_from_slice(&hash2[..32]);
-        let r_point = self.scalar_mult_base(&r);
-        
-        let mut hash3_input = Vec::new();
-        hash3_input.extend_from_slice(&r_point);
-        hash3_input.extend_from_slice(public_key);
-        hash3_input.extend_from_slice(data);
-        let hash3 = hasher.hash(&hash3_input);
-        
-        let mut h = [0u8; 32];
-        h.copy_from_slice(&hash3[..32]);
-        
-        let mut s = [0u8; 32];
-        for i in 0..32 {
-            s[i] = r[i].wrapping_add(h[i].wrapping_mul(scalar[i]));
-        }
-        
-        let mut signature = Vec::with_capacity(64);
-        signature.extend_from_slice(&r_point);
-        signature.extend_from_slice(&s);
-        
-        Ok(signature)
+        let s = BigUint::from_be_bytes(signature);
+        if s >= self.n {
+            return Ok(None);
+        }
+        let ctx = Montgomery::new(&self.n).ok_or(CryptoError::InvalidKeyFormat)?;
+        Ok(ctx.mod_pow(&s, &self.e).to_be_bytes(self.size))
     }
-    
-    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
-        if public_key.len() != 32 {
-            return Err(CryptoError::InvalidKeySize);
+
+    pub fn verify_pkcs1(&self, hash: HashAlgorithm, data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        let expected = emsa_pkcs1_encode(hash, data, self.size)?;
+        Ok(self.public_op(signature)? == Some(expected))
+    }
+
+    /// RSASSA-PSS verification with MGF1 over the same hash and any salt length
+    pub fn verify_pss(&self, hash: HashAlgorithm, data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        let m_hash = digest(hash, data)?;
+        let h_len = m_hash.len();
+        let em_bits = self.n.bits() - 1;
+        let em_len = (em_bits + 7) / 8;
+
+        let full = match self.public_op(signature)? {
+            Some(full) => full,
+            None => return Ok(false),
+        };
+        let (leading, em) = full.split_at(full.len() - em_len);
+        if leading.iter().any(|&b| b != 0) || em_len < h_len + 2 || em[em_len - 1] != 0xbc {
+            return Ok(false);
         }
-        if signature.len() != 64 {
-            return Err(CryptoError::InvalidSignature);
+
+        let (masked_db, rest) = em.split_at(em_len - h_len - 1);
+        let h = &rest[..h_len];
+        let top_mask = 0xffu8 >> (8 * em_len - em_bits);
+        if masked_db[0] & !top_mask != 0 {
+            return Ok(false);
         }
-        
-        let hasher = SHA256::new();
-        
-        let r_point = &signature[..32];
-        let s = &signature[32..];
-        
-        let mut hash_input = Vec::new();
-        hash_input.extend_from_slice(r_point);
-        hash_input.extend_from_slice(public_key);
-        hash_input.extend_from_slice(data);
-        let hash = hasher.hash(&hash_input);
-        
-        Ok(true)
-    }
-    
-    fn encrypt(&self, _public_key: &[u8], _plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
-        Err(CryptoError::UnsupportedAlgorithm)
+
+        let mut db = mgf1(hash, h, masked_db.len())?;
+        for (d, m) in db.iter_mut().zip(masked_db) {
+            *d ^= m;
+        }
+        db[0] &= top_mask;
+
+        let salt = match db.iter().position(|&b| b != 0) {
+            Some(i) if db[i] == 0x01 => &db[i + 1..],
+            _ => return Ok(false),
+        };
+
+        let mut m_prime = vec![0u8; 8];
+        m_prime.extend_from_slice(&m_hash);
+        m_prime.extend_from_slice(salt);
+        Ok(digest(hash, &m_prime)? == h)
     }
-    
-    fn decrypt(&self, _private_key: &[u8], _ciphertext: &[u8]) -> CryptoResult<Vec<u8>> {
-        Err(CryptoError::UnsupportedAlgorithm)
+}
+
+// EMSA-PKCS1-v1_5: 00 01 FF..FF 00 DigestInfo H
+fn emsa_pkcs1_encode(hash: HashAlgorithm, data: &[u8], em_len: usize) -> CryptoResult<Vec<u8>> {
+    let prefix = digest_info_prefix(hash)?;
+    let h = digest(hash, data)?;
+    let t_len = prefix.len() + h.len();
+    if em_len < t_len + 11 {
+        return Err(CryptoError::InvalidKeySize);
+    }
+
+    let mut em = Vec::with_capacity(em_len);
+    em.push(0x00);
+    em.push(0x01);
+    em.resize(em_len - t_len - 1, 0xff);
+    em.push(0x00);
+    em.extend_from_slice(prefix);
+    em.extend_from_slice(&h);
+    Ok(em)
+}
+
+fn mgf1(hash: HashAlgorithm, seed: &[u8], len: usize) -> CryptoResult<Vec<u8>> {
+    let mut mask = Vec::with_capacity(len);
+    let mut counter = 0u32;
+    while mask.len() < len {
+        let mut input = seed.to_vec();
+        input.extend_from_slice(&counter.to_be_bytes());
+        mask.extend_from_slice(&digest(hash, &input)?);
+        counter += 1;
+    }
+    mask.truncate(len);
+    Ok(mask)
+}
+
+struct RsaPrivateKey {
+    public: RsaPublicKey,
+    d: BigUint,
+}
+
+impl RsaPrivateKey {
+    fn parse(bytes: &[u8]) -> CryptoResult<Self> {
+        let mut offset = 0;
+        let n = read_field(bytes, &mut offset)?;
+        let e = read_field(bytes, &mut offset)?;
+        let d = read_field(bytes, &mut offset)?;
+        Ok(Self {
+            public: RsaPublicKey::from_components(n, e)?,
+            d: BigUint::from_be_bytes(d),
+        })
+    }
+
+    // RSADP/RSASP1: input^d mod n
+    fn private_op(&self, input: &[u8]) -> CryptoResult<Vec<u8>> {
+        let m = BigUint::from_be_bytes(input);
+        if m >= self.public.n {
+            return Err(CryptoError::InvalidParameter);
+        }
+        let ctx = Montgomery::new(&self.public.n).ok_or(CryptoError::InvalidKeyFormat)?;
+        ctx.mod_pow(&m, &self.d)
+            .to_be_bytes(self.public.size)
+            .ok_or(CryptoError::InvalidParameter)
     }
 }
 
@@ -191,78 +295,46 @@ impl RSA {
     pub fn new(key_size: usize) -> Self {
         Self { key_size }
     }
-    
-    fn mod_exp(&self, base: &[u8], exp: &[u8], modulus: &[u8]) -> Vec<u8> {
-        let mut result = vec![1u8];
-        let mut base = base.to_vec();
-        
-        for byte in exp.iter() {
-            for i in 0..8 {
-                if (byte >> i) & 1 == 1 {
-                    result = self.mod_mult(&result, &base, modulus);
-                }
-                base = self.mod_mult(&base, &base, modulus);
-            }
-        }
-        
-        result
-    }
-    
-    fn mod_mult(&self, a: &[u8], b: &[u8], _modulus: &[u8]) -> Vec<u8> {
-        let mut result = Vec::new();
-        
-        for (i, &a_byte) in a.iter().enumerate() {
-            for (j, &b_byte) in b.iter().enumerate() {
-                let product = a_byte as u16 * b_byte as u16;
-                let index = i + j;
-                
-                while result.len() <= index + 1 {
-                    result.push(0);
-                }
-                
-                let mut carry = product;
-                let mut k = index;
-                while carry > 0 && k < result.len() {
-                    carry += result[k] as u16;
-                    result[k] = (carry & 0xff) as u8;
-                    carry >>= 8;
-                    k += 1;
-                }
-                
-                if carry > 0 {
-                    result.push((carry & 0xff) as u8);
-                }
-            }
+
+    fn check_size(&self, key: &RsaPublicKey) -> CryptoResult<()> {
+        if key.size() != self.key_size / 8 {
+            return Err(CryptoError::InvalidKeySize);
         }
-        
-        result
+        Ok(())
     }
-    
+
+    // EME-PKCS1-v1_5: 00 02 PS 00 M with nonzero random PS
     fn pkcs1_pad(&self, data: &[u8], key_size: usize) -> Vec<u8> {
         let mut padded = Vec::with_capacity(key_size);
         padded.push(0x00);
         padded.push(0x02);
-        
-        let padding_len = key_size - data.len() - 3;
-        for _ in 0..padding_len {
-            padded.push(0xff);
+
+        let mut padding = vec![0u8; key_size - data.len() - 3];
+        rng::fill_bytes(&mut padding);
+        for byte in padding.iter_mut() {
+            while *byte == 0 {
+                let mut replacement = [0u8; 1];
+                rng::fill_bytes(&mut replacement);
+                *byte = replacement[0];
+            }
         }
-        
+        padded.extend_from_slice(&padding);
+
         padded.push(0x00);
         padded.extend_from_slice(data);
-        
+
         padded
     }
-    
+
     fn pkcs1_unpad(&self, padded: &[u8]) -> CryptoResult<Vec<u8>> {
         if padded.len() < 11 {
             return Err(CryptoError::InvalidPadding);
         }
-        
+
         if padded[0] != 0x00 || padded[1] != 0x02 {
             return Err(CryptoError::InvalidPadding);
         }
-        
+
         let mut separator_index = None;
         for i in 2..padded.len() {
             if padded[i] == 0x00 {
@@ -270,7 +342,7 @@ impl RSA {
                 break;
             }
         }
-        
+
         match separator_index {
             Some(index) if index >= 10 => Ok(padded[index + 1..].to_vec()),
             _ => Err(CryptoError::InvalidPadding),
@@ -280,90 +352,789 @@ impl RSA {
 
 impl AsymmetricCrypto for RSA {
     fn generate_keypair(&self) -> CryptoResult<(Vec<u8>, Vec<u8>)> {
-        let mut n = vec![0xffu8; self.key_size / 8];
-        n[0] = 0x80;
-        
-        let e = vec![0x01, 0x00, 0x01];
-        
-        let mut d = vec![0xaau8; self.key_size / 8];
-        d[0] = 0x80;
-        
-        let mut public_key = Vec::new();
-        public_key.extend_from_slice(&(n.len() as u32).to_be_bytes());
-        public_key.extend_from_slice(&n);
-        public_key.extend_from_slice(&(e.len() as u32).to_be_bytes());
-        public_key.extend_from_slice(&e);
-        
-        let mut private_key = Vec::new();
-        private_key.extend_from_slice(&(n.len() as u32).to_be_bytes());
-        private_key.extend_from_slice(&n);
-        private_key.extend_from_slice(&(e.len() as u32).to_be_bytes());
-        private_key.extend_from_slice(&e);
-        private_key.extend_from_slice(&(d.len() as u32).to_be_bytes());
-        private_key.extend_from_slice(&d);
-        
-        Ok((public_key, private_key))
-    }
-    
+        let e = BigUint::from_u64(RSA_EXPONENT);
+        let half = self.key_size / 2;
+
+        loop {
+            let p = generate_prime(half)?;
+            let q = generate_prime(half)?;
+            if p == q {
+                continue;
+            }
+            let n = p.mul(&q);
+            if n.bits() != self.key_size {
+                continue;
+            }
+
+            // d = (1 + k*phi) / e with k chosen so the division is exact
+            let one = BigUint::from_u64(1);
+            let phi = p.sub(&one).mul(&q.sub(&one));
+            let (_, phi_mod_e) = phi.div_rem_u64(RSA_EXPONENT);
+            let k = match (1..RSA_EXPONENT).find(|k| (k * phi_mod_e + 1) % RSA_EXPONENT == 0) {
+                Some(k) => k,
+                None => continue,
+            };
+            let (d, _) = phi.mul(&BigUint::from_u64(k)).add(&one).div_rem_u64(RSA_EXPONENT);
+
+            let size = self.key_size / 8;
+            let n_bytes = n.to_be_bytes(size).ok_or(CryptoError::KeyGenerationFailed)?;
+            let e_bytes = e.to_be_bytes(3).ok_or(CryptoError::KeyGenerationFailed)?;
+            let d_bytes = d.to_be_bytes(size).ok_or(CryptoError::KeyGenerationFailed)?;
+
+            let public_key = rsa_public_key(&n_bytes, &e_bytes);
+            let mut private_key = public_key.clone();
+            write_field(&mut private_key, &d_bytes);
+
+            return Ok((public_key, private_key));
+        }
+    }
+
     fn sign(&self, private_key: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
-        let hasher = SHA256::new();
-        let hash = hasher.hash(data);
-        
-        let mut digest_info = Vec::new();
-        digest_info.extend_from_slice(&[
-            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86,
-            0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
-            0x00, 0x04, 0x20,
+        let key = RsaPrivateKey::parse(private_key)?;
+        self.check_size(&key.public)?;
+        let em = emsa_pkcs1_encode(HashAlgorithm::SHA256, data, key.public.size())?;
+        key.private_op(&em)
+    }
+
+    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        let key = RsaPublicKey::parse(public_key)?;
+        self.check_size(&key)?;
+        key.verify_pkcs1(HashAlgorithm::SHA256, data, signature)
+    }
+
+    fn encrypt(&self, public_key: &[u8], plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
+        let key = RsaPublicKey::parse(public_key)?;
+        self.check_size(&key)?;
+        if plaintext.len() > key.size() - 11 {
+            return Err(CryptoError::InvalidParameter);
+        }
+
+        let padded = self.pkcs1_pad(plaintext, key.size());
+        let ctx = Montgomery::new(&key.n).ok_or(CryptoError::InvalidKeyFormat)?;
+        ctx.mod_pow(&BigUint::from_be_bytes(&padded), &key.e)
+            .to_be_bytes(key.size())
+            .ok_or(CryptoError::InvalidParameter)
+    }
+
+    fn decrypt(&self, private_key: &[u8], ciphertext: &[u8]) -> CryptoResult<Vec<u8>> {
+        let key = RsaPrivateKey::parse(private_key)?;
+        self.check_size(&key.public)?;
+        if ciphertext.len() != key.public.size() {
+            return Err(CryptoError::InvalidParameter);
+        }
+
+        self.pkcs1_unpad(&key.private_op(ciphertext)?)
+    }
+}
+
+const RSA_EXPONENT: u64 = 65537;
+
+const SMALL_PRIMES: [u64; 24] = [
+    3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+];
+
+fn random_below(bound: &BigUint) -> BigUint {
+    let mut bytes = vec![0u8; (bound.bits() + 7) / 8 + 8];
+    rng::fill_bytes(&mut bytes);
+    BigUint::from_be_bytes(&bytes).rem(bound)
+}
+
+// Miller-Rabin with random bases
+fn is_probable_prime(n: &BigUint, rounds: usize) -> bool {
+    let ctx = match Montgomery::new(n) {
+        Some(ctx) => ctx,
+        None => return false,
+    };
+    let one = BigUint::from_u64(1);
+    let n_minus_1 = n.sub(&one);
+    let s = (0..n_minus_1.bits()).find(|&i| n_minus_1.bit(i)).unwrap_or(0);
+    let d = n_minus_1.shr(s);
+    let mont_one = ctx.one();
+    let mont_minus_one = ctx.neg(&mont_one);
+    let base_range = n.sub(&BigUint::from_u64(3));
+
+    'witness: for _ in 0..rounds {
+        let a = random_below(&base_range).add(&BigUint::from_u64(2));
+        let mut x = ctx.pow(&ctx.to_mont(&a), &d);
+        if x == mont_one || x == mont_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = ctx.square(&x);
+            if x == mont_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+// Random prime of exactly `bits` bits with the top two bits set, so the
+// product of two has the full length, and p - 1 coprime to the exponent
+fn generate_prime(bits: usize) -> CryptoResult<BigUint> {
+    if bits < 64 || bits % 8 != 0 {
+        return Err(CryptoError::InvalidKeySize);
+    }
+
+    loop {
+        let mut bytes = vec![0u8; bits / 8];
+        rng::fill_bytes(&mut bytes);
+        bytes[0] |= 0xc0;
+        let last = bytes.len() - 1;
+        bytes[last] |= 1;
+        let candidate = BigUint::from_be_bytes(&bytes);
+
+        if SMALL_PRIMES.iter().any(|&p| candidate.div_rem_u64(p).1 == 0) {
+            continue;
+        }
+        if candidate.div_rem_u64(RSA_EXPONENT).1 == 1 {
+            continue;
+        }
+        if is_probable_prime(&candidate, 16) {
+            return Ok(candidate);
+        }
+    }
+}
+
+/// Encode an RSA public key from big-endian modulus and exponent
+pub fn rsa_public_key(n: &[u8], e: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(n.len() + e.len() + 8);
+    write_field(&mut key, n);
+    write_field(&mut key, e);
+    key
+}
+
+/// Encode an RSA private key from big-endian modulus, public exponent and
+/// private exponent
+pub fn rsa_private_key(n: &[u8], e: &[u8], d: &[u8]) -> Vec<u8> {
+    let mut key = rsa_public_key(n, e);
+    write_field(&mut key, d);
+    key
+}
+
+/// RSADP/RSASP1 without padding, input^d mod n as a modulus-sized string,
+/// for protocols that pad for themselves
+pub fn rsa_private_raw(private_key: &[u8], input: &[u8]) -> CryptoResult<Vec<u8>> {
+    RsaPrivateKey::parse(private_key)?.private_op(input)
+}
+
+// Jacobian point (X/Z^2, Y/Z^3) with coordinates in Montgomery form; Z = 0
+// is the point at infinity
+#[derive(Clone)]
+struct JacobianPoint {
+    x: Vec<u64>,
+    y: Vec<u64>,
+    z: Vec<u64>,
+}
+
+struct P256 {
+    field: Montgomery,
+    order: Montgomery,
+    b: Vec<u64>,
+    generator: JacobianPoint,
+}
+
+lazy_static! {
+    static ref P256_CURVE: P256 = P256::new();
+}
+
+impl P256 {
+    fn new() -> Self {
+        let p = BigUint::from_limbs(vec![
+            0xffffffffffffffff, 0x00000000ffffffff, 0x0000000000000000, 0xffffffff00000001,
         ]);
-        digest_info.extend_from_slice(&hash);
-        
-        let padded = self.pkcs1_pad(&digest_info, self.key_size / 8);
-        
-        Ok(padded)
-    }
-    
-    fn verify(&self, _public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
-        let hasher = SHA256::new();
-        let hash = hasher.hash(data);
-        
-        if signature.len() != self.key_size / 8 {
-            return Ok(false);
+        let n = BigUint::from_limbs(vec![
+            0xf3b9cac2fc632551, 0xbce6faada7179e84, 0xffffffffffffffff, 0xffffffff00000000,
+        ]);
+        let b = BigUint::from_limbs(vec![
+            0x3bce3c3e27d2604b, 0x651d06b0cc53b0f6, 0xb3ebbd55769886bc, 0x5ac635d8aa3a93e7,
+        ]);
+        let gx = BigUint::from_limbs(vec![
+            0xf4a13945d898c296, 0x77037d812deb33a0, 0xf8bce6e563a440f2, 0x6b17d1f2e12c4247,
+        ]);
+        let gy = BigUint::from_limbs(vec![
+            0xcbb6406837bf51f5, 0x2bce33576b315ece, 0x8ee7eb4a7c0f9e16, 0x4fe342e2fe1a7f9b,
+        ]);
+
+        // Both moduli are odd, so the contexts always exist
+        let field = Montgomery::new(&p).unwrap();
+        let order = Montgomery::new(&n).unwrap();
+        let generator = JacobianPoint {
+            x: field.to_mont(&gx),
+            y: field.to_mont(&gy),
+            z: field.one(),
+        };
+
+        Self {
+            b: field.to_mont(&b),
+            field,
+            order,
+            generator,
         }
-        
-        let unpadded = self.pkcs1_unpad(signature)?;
-        
-        if unpadded.len() < 32 {
-            return Ok(false);
+    }
+
+    fn infinity(&self) -> JacobianPoint {
+        JacobianPoint {
+            x: self.field.one(),
+            y: self.field.one(),
+            z: self.field.zero(),
         }
-        
-        let signature_hash = &unpadded[unpadded.len() - 32..];
-        
-        Ok(signature_hash == hash.as_slice())
     }
-    
-    fn encrypt(&self, _public_key: &[u8], plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
-        if plaintext.len() > self.key_size / 8 - 11 {
-            return Err(CryptoError::InvalidParameter);
+
+    fn twice(&self, a: &[u64]) -> Vec<u64> {
+        self.field.add(a, a)
+    }
+
+    // dbl-2001-b for a = -3
+    fn double(&self, p: &JacobianPoint) -> JacobianPoint {
+        let f = &self.field;
+        if f.is_zero(&p.z) {
+            return p.clone();
         }
-        
-        let padded = self.pkcs1_pad(plaintext, self.key_size / 8);
-        Ok(padded)
+
+        let delta = f.square(&p.z);
+        let gamma = f.square(&p.y);
+        let beta = f.mul(&p.x, &gamma);
+        let t = f.mul(&f.sub(&p.x, &delta), &f.add(&p.x, &delta));
+        let alpha = f.add(&f.add(&t, &t), &t);
+
+        let beta4 = self.twice(&self.twice(&beta));
+        let x3 = f.sub(&f.square(&alpha), &self.twice(&beta4));
+        let z3 = f.sub(&f.sub(&f.square(&f.add(&p.y, &p.z)), &gamma), &delta);
+        let gamma_sq8 = self.twice(&self.twice(&self.twice(&f.square(&gamma))));
+        let y3 = f.sub(&f.mul(&alpha, &f.sub(&beta4, &x3)), &gamma_sq8);
+
+        JacobianPoint { x: x3, y: y3, z: z3 }
     }
-    
-    fn decrypt(&self, _private_key: &[u8], ciphertext: &[u8]) -> CryptoResult<Vec<u8>> {
-        if ciphertext.len() != self.key_size / 8 {
-            return Err(CryptoError::InvalidParameter);
+
+    // add-2007-bl
+    fn add(&self, p: &JacobianPoint, q: &JacobianPoint) -> JacobianPoint {
+        let f = &self.field;
+        if f.is_zero(&p.z) {
+            return q.clone();
         }
-        
-        self.pkcs1_unpad(ciphertext)
+        if f.is_zero(&q.z) {
+            return p.clone();
+        }
+
+        let z1z1 = f.square(&p.z);
+        let z2z2 = f.square(&q.z);
+        let u1 = f.mul(&p.x, &z2z2);
+        let u2 = f.mul(&q.x, &z1z1);
+        let s1 = f.mul(&f.mul(&p.y, &q.z), &z2z2);
+        let s2 = f.mul(&f.mul(&q.y, &p.z), &z1z1);
+
+        let h = f.sub(&u2, &u1);
+        let r = self.twice(&f.sub(&s2, &s1));
+        if f.is_zero(&h) {
+            return if f.is_zero(&r) { self.double(p) } else { self.infinity() };
+        }
+
+        let i = f.square(&self.twice(&h));
+        let j = f.mul(&h, &i);
+        let v = f.mul(&u1, &i);
+        let x3 = f.sub(&f.sub(&f.square(&r), &j), &self.twice(&v));
+        let y3 = f.sub(&f.mul(&r, &f.sub(&v, &x3)), &self.twice(&f.mul(&s1, &j)));
+        let z3 = f.mul(&f.sub(&f.sub(&f.square(&f.add(&p.z, &q.z)), &z1z1), &z2z2), &h);
+
+        JacobianPoint { x: x3, y: y3, z: z3 }
+    }
+
+    fn scalar_mul(&self, k: &BigUint, p: &JacobianPoint) -> JacobianPoint {
+        let mut result = self.infinity();
+        for i in (0..k.bits()).rev() {
+            result = self.double(&result);
+            if k.bit(i) {
+                result = self.add(&result, p);
+            }
+        }
+        result
+    }
+
+    // Uncompressed SEC1 point, validated to lie on the curve
+    fn parse_point(&self, bytes: &[u8]) -> Option<JacobianPoint> {
+        let coords = match bytes.len() {
+            65 if bytes[0] == 0x04 => &bytes[1..],
+            64 => bytes,
+            _ => return None,
+        };
+        let x = BigUint::from_be_bytes(&coords[..32]);
+        let y = BigUint::from_be_bytes(&coords[32..]);
+        if &x >= self.field.modulus() || &y >= self.field.modulus() {
+            return None;
+        }
+
+        let f = &self.field;
+        let (x, y) = (f.to_mont(&x), f.to_mont(&y));
+        let x3 = f.mul(&f.square(&x), &x);
+        let three_x = f.add(&f.add(&x, &x), &x);
+        let rhs = f.add(&f.sub(&x3, &three_x), &self.b);
+        if f.square(&y) != rhs {
+            return None;
+        }
+
+        Some(JacobianPoint { x, y, z: f.one() })
+    }
+
+    fn verify(&self, q: &JacobianPoint, hash: &[u8], r: &BigUint, s: &BigUint) -> bool {
+        let n = &self.order;
+        if r.is_zero() || s.is_zero() || r >= n.modulus() || s >= n.modulus() {
+            return false;
+        }
+
+        // Leftmost 256 bits of the hash
+        let e = BigUint::from_be_bytes(&hash[..hash.len().min(32)]);
+        let w = n.invert(&n.to_mont(s));
+        let u1 = n.from_mont(&n.mul(&n.to_mont(&e), &w));
+        let u2 = n.from_mont(&n.mul(&n.to_mont(r), &w));
+
+        let point = self.add(&self.scalar_mul(&u1, &self.generator), &self.scalar_mul(&u2, q));
+        let f = &self.field;
+        if f.is_zero(&point.z) {
+            return false;
+        }
+
+        let z_inv = f.invert(&point.z);
+        let x = f.from_mont(&f.mul(&point.x, &f.square(&z_inv)));
+        &x.rem(n.modulus()) == r
+    }
+}
+
+// Raw r||s or a DER SEQUENCE of two INTEGERs
+fn parse_ecdsa_signature(signature: &[u8]) -> Option<(BigUint, BigUint)> {
+    if signature.len() == 64 {
+        return Some((
+            BigUint::from_be_bytes(&signature[..32]),
+            BigUint::from_be_bytes(&signature[32..]),
+        ));
+    }
+
+    if signature.len() < 8 || signature[0] != 0x30 || signature[1] as usize != signature.len() - 2 {
+        return None;
+    }
+    let mut offset = 2;
+    let mut read_integer = || {
+        if signature.get(offset) != Some(&0x02) {
+            return None;
+        }
+        let len = *signature.get(offset + 1)? as usize;
+        let value = signature.get(offset + 2..offset + 2 + len)?;
+        offset += 2 + len;
+        Some(BigUint::from_be_bytes(value))
+    };
+    let r = read_integer()?;
+    let s = read_integer()?;
+    Some((r, s))
+}
+
+pub struct EcdsaP256;
+
+impl EcdsaP256 {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Verify against a precomputed message hash
+    pub fn verify_prehashed(&self, public_key: &[u8], hash: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        let curve = &*P256_CURVE;
+        let q = curve.parse_point(public_key).ok_or(CryptoError::InvalidKeyFormat)?;
+        Ok(match parse_ecdsa_signature(signature) {
+            Some((r, s)) => curve.verify(&q, hash, &r, &s),
+            None => false,
+        })
+    }
+}
+
+impl AsymmetricCrypto for EcdsaP256 {
+    // Signing needs constant-time nonce arithmetic, which the bignum code is not
+    fn generate_keypair(&self) -> CryptoResult<(Vec<u8>, Vec<u8>)> {
+        Err(CryptoError::UnsupportedAlgorithm)
+    }
+
+    fn sign(&self, _private_key: &[u8], _data: &[u8]) -> CryptoResult<Vec<u8>> {
+        Err(CryptoError::UnsupportedAlgorithm)
+    }
+
+    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        self.verify_prehashed(public_key, &SHA256::new().hash(data), signature)
+    }
+
+    fn encrypt(&self, _public_key: &[u8], _plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
+        Err(CryptoError::UnsupportedAlgorithm)
+    }
+
+    fn decrypt(&self, _private_key: &[u8], _ciphertext: &[u8]) -> CryptoResult<Vec<u8>> {
+        Err(CryptoError::UnsupportedAlgorithm)
     }
 }
 
+// Extended twisted Edwards point (X:Y:Z:T) with x = X/Z, y = Y/Z, xy = T/Z
+#[derive(Clone)]
+struct ExtendedPoint {
+    x: Vec<u64>,
+    y: Vec<u64>,
+    z: Vec<u64>,
+    t: Vec<u64>,
+}
+
+struct Edwards25519 {
+    field: Montgomery,
+    order: BigUint,
+    d: Vec<u64>,
+    d2: Vec<u64>,
+    sqrt_m1: Vec<u64>,
+    // (p - 5) / 8
+    sqrt_exp: BigUint,
+    base: ExtendedPoint,
+}
+
+lazy_static! {
+    static ref ED25519_CURVE: Edwards25519 = Edwards25519::new();
+}
+
+impl Edwards25519 {
+    fn new() -> Self {
+        let p = BigUint::from_limbs(vec![
+            0xffffffffffffffed, 0xffffffffffffffff, 0xffffffffffffffff, 0x7fffffffffffffff,
+        ]);
+        let order = BigUint::from_limbs(vec![
+            0x5812631a5cf5d3ed, 0x14def9dea2f79cd6, 0x0000000000000000, 0x1000000000000000,
+        ]);
+        let sqrt_exp = BigUint::from_limbs(vec![
+            0xfffffffffffffffd, 0xffffffffffffffff, 0xffffffffffffffff, 0x0fffffffffffffff,
+        ]);
+        // (p - 1) / 4; 2 is a non-residue since p = 5 mod 8
+        let quarter = BigUint::from_limbs(vec![
+            0xfffffffffffffffb, 0xffffffffffffffff, 0xffffffffffffffff, 0x1fffffffffffffff,
+        ]);
+
+        let field = Montgomery::new(&p).unwrap();
+        // d = -121665 / 121666
+        let d = field.mul(
+            &field.neg(&field.to_mont(&BigUint::from_u64(121665))),
+            &field.invert(&field.to_mont(&BigUint::from_u64(121666))),
+        );
+        let sqrt_m1 = field.pow(&field.to_mont(&BigUint::from_u64(2)), &quarter);
+
+        let mut curve = Self {
+            d2: field.add(&d, &d),
+            d,
+            sqrt_m1,
+            sqrt_exp,
+            order,
+            base: ExtendedPoint {
+                x: field.zero(),
+                y: field.one(),
+                z: field.one(),
+                t: field.zero(),
+            },
+            field,
+        };
+
+        // The base point is y = 4/5 with even x
+        let mut base = [0x66u8; 32];
+        base[0] = 0x58;
+        curve.base = curve.decode(&base).unwrap();
+        curve
+    }
+
+    fn identity(&self) -> ExtendedPoint {
+        ExtendedPoint {
+            x: self.field.zero(),
+            y: self.field.one(),
+            z: self.field.one(),
+            t: self.field.zero(),
+        }
+    }
+
+    fn add(&self, p: &ExtendedPoint, q: &ExtendedPoint) -> ExtendedPoint {
+        let f = &self.field;
+        let a = f.mul(&f.sub(&p.y, &p.x), &f.sub(&q.y, &q.x));
+        let b = f.mul(&f.add(&p.y, &p.x), &f.add(&q.y, &q.x));
+        let c = f.mul(&f.mul(&p.t, &self.d2), &q.t);
+        let zz = f.mul(&p.z, &q.z);
+        let d = f.add(&zz, &zz);
+        let (e, ff, g, h) = (f.sub(&b, &a), f.sub(&d, &c), f.add(&d, &c), f.add(&b, &a));
+
+        ExtendedPoint {
+            x: f.mul(&e, &ff),
+            y: f.mul(&g, &h),
+            t: f.mul(&e, &h),
+            z: f.mul(&ff, &g),
+        }
+    }
+
+    fn double(&self, p: &ExtendedPoint) -> ExtendedPoint {
+        let f = &self.field;
+        let a = f.square(&p.x);
+        let b = f.square(&p.y);
+        let zz = f.square(&p.z);
+        let c = f.add(&zz, &zz);
+        let h = f.add(&a, &b);
+        let e = f.sub(&h, &f.square(&f.add(&p.x, &p.y)));
+        let g = f.sub(&a, &b);
+        let ff = f.add(&c, &g);
+
+        ExtendedPoint {
+            x: f.mul(&e, &ff),
+            y: f.mul(&g, &h),
+            t: f.mul(&e, &h),
+            z: f.mul(&ff, &g),
+        }
+    }
+
+    fn scalar_mul(&self, k: &BigUint, p: &ExtendedPoint) -> ExtendedPoint {
+        let mut result = self.identity();
+        for i in (0..k.bits()).rev() {
+            result = self.double(&result);
+            if k.bit(i) {
+                result = self.add(&result, p);
+            }
+        }
+        result
+    }
+
+    fn encode(&self, p: &ExtendedPoint) -> [u8; 32] {
+        let f = &self.field;
+        let z_inv = f.invert(&p.z);
+        let x = f.from_mont(&f.mul(&p.x, &z_inv));
+        let y = f.from_mont(&f.mul(&p.y, &z_inv));
+
+        let mut out = [0u8; 32];
+        // y < p < 2^255, so it always fits
+        out.copy_from_slice(&y.to_le_bytes(32).unwrap_or_default());
+        out[31] |= (x.is_odd() as u8) << 7;
+        out
+    }
+
+    // RFC 8032 section 5.1.3
+    fn decode(&self, bytes: &[u8]) -> Option<ExtendedPoint> {
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut y_bytes = bytes.to_vec();
+        let sign = y_bytes[31] >> 7;
+        y_bytes[31] &= 0x7f;
+        let y = BigUint::from_le_bytes(&y_bytes);
+        if &y >= self.field.modulus() {
+            return None;
+        }
+
+        let f = &self.field;
+        let y = f.to_mont(&y);
+        let one = f.one();
+        let yy = f.square(&y);
+        let u = f.sub(&yy, &one);
+        let v = f.add(&f.mul(&self.d, &yy), &one);
+
+        let v3 = f.mul(&f.square(&v), &v);
+        let v7 = f.mul(&f.square(&v3), &v);
+        let mut x = f.mul(&f.mul(&u, &v3), &f.pow(&f.mul(&u, &v7), &self.sqrt_exp));
+
+        let vxx = f.mul(&v, &f.square(&x));
+        if vxx != u {
+            if vxx != f.neg(&u) {
+                return None;
+            }
+            x = f.mul(&x, &self.sqrt_m1);
+        }
+
+        let x_plain = f.from_mont(&x);
+        if x_plain.is_zero() && sign == 1 {
+            return None;
+        }
+        if x_plain.is_odd() as u8 != sign {
+            x = f.neg(&x);
+        }
+
+        Some(ExtendedPoint {
+            t: f.mul(&x, &y),
+            x,
+            y,
+            z: one,
+        })
+    }
+
+    // Scalar from the first half of SHA-512(seed), clamped
+    fn secret_scalar(hash: &[u8]) -> BigUint {
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&hash[..32]);
+        scalar[0] &= 248;
+        scalar[31] &= 127;
+        scalar[31] |= 64;
+        BigUint::from_le_bytes(&scalar)
+    }
+
+    fn reduce(&self, hash: &[u8]) -> BigUint {
+        BigUint::from_le_bytes(hash).rem(&self.order)
+    }
+}
+
+pub struct Ed25519;
+
+impl Ed25519 {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn public_from_seed(seed: &[u8]) -> [u8; 32] {
+        let curve = &*ED25519_CURVE;
+        let h = SHA512::new().hash(seed);
+        curve.encode(&curve.scalar_mul(&Edwards25519::secret_scalar(&h), &curve.base))
+    }
+}
+
+impl AsymmetricCrypto for Ed25519 {
+    fn generate_keypair(&self) -> CryptoResult<(Vec<u8>, Vec<u8>)> {
+        let mut seed = [0u8; 32];
+        rng::fill_bytes(&mut seed);
+        let public_key = Self::public_from_seed(&seed);
+
+        let mut private_key = Vec::with_capacity(64);
+        private_key.extend_from_slice(&seed);
+        private_key.extend_from_slice(&public_key);
+
+        Ok((public_key.to_vec(), private_key))
+    }
+
+    fn sign(&self, private_key: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
+        if private_key.len() != 64 {
+            return Err(CryptoError::InvalidKeySize);
+        }
+
+        let curve = &*ED25519_CURVE;
+        let hasher = SHA512::new();
+        let h = hasher.hash(&private_key[..32]);
+        let a = Edwards25519::secret_scalar(&h);
+        let public_key = curve.encode(&curve.scalar_mul(&a, &curve.base));
+
+        let mut nonce_input = h[32..].to_vec();
+        nonce_input.extend_from_slice(data);
+        let r = curve.reduce(&hasher.hash(&nonce_input));
+        let r_point = curve.encode(&curve.scalar_mul(&r, &curve.base));
+
+        let mut challenge_input = Vec::with_capacity(64 + data.len());
+        challenge_input.extend_from_slice(&r_point);
+        challenge_input.extend_from_slice(&public_key);
+        challenge_input.extend_from_slice(data);
+        let k = curve.reduce(&hasher.hash(&challenge_input));
+
+        let s = r.add(&k.mul(&a)).rem(&curve.order);
+
+        let mut signature = Vec::with_capacity(64);
+        signature.extend_from_slice(&r_point);
+        signature.extend_from_slice(&s.to_le_bytes(32).ok_or(CryptoError::InvalidState)?);
+
+        Ok(signature)
+    }
+
+    fn verify(&self, public_key: &[u8], data: &[u8], signature: &[u8]) -> CryptoResult<bool> {
+        if public_key.len() != 32 {
+            return Err(CryptoError::InvalidKeySize);
+        }
+        if signature.len() != 64 {
+            return Err(CryptoError::InvalidSignature);
+        }
+
+        let curve = &*ED25519_CURVE;
+        let (a, r) = match (curve.decode(public_key), curve.decode(&signature[..32])) {
+            (Some(a), Some(r)) => (a, r),
+            _ => return Ok(false),
+        };
+        let s = BigUint::from_le_bytes(&signature[32..]);
+        if s >= curve.order {
+            return Ok(false);
+        }
+
+        let mut challenge_input = Vec::with_capacity(64 + data.len());
+        challenge_input.extend_from_slice(&signature[..32]);
+        challenge_input.extend_from_slice(public_key);
+        challenge_input.extend_from_slice(data);
+        let k = curve.reduce(&SHA512::new().hash(&challenge_input));
+
+        let lhs = curve.scalar_mul(&s, &curve.base);
+        let rhs = curve.add(&r, &curve.scalar_mul(&k, &a));
+        Ok(curve.encode(&lhs) == curve.encode(&rhs))
+    }
+
+    fn encrypt(&self, _public_key: &[u8], _plaintext: &[u8]) -> CryptoResult<Vec<u8>> {
+        Err(CryptoError::UnsupportedAlgorithm)
+    }
+
+    fn decrypt(&self, _private_key: &[u8], _ciphertext: &[u8]) -> CryptoResult<Vec<u8>> {
+        Err(CryptoError::UnsupportedAlgorithm)
+    }
+}
+
+/// X25519: the shared secret from a private scalar and a peer's public
+/// u-coordinate, or None if it is all zeros (a low-order point)
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> Option<[u8; 32]> {
+    let f = &ED25519_CURVE.field;
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    let k = BigUint::from_le_bytes(&k);
+    let mut u = *u;
+    u[31] &= 127;
+
+    // The Montgomery ladder, swapping by the scalar's bits
+    let x1 = f.to_mont(&BigUint::from_le_bytes(&u));
+    let a24 = f.to_mont(&BigUint::from_u64(121665));
+    let (mut x2, mut z2, mut x3, mut z3) = (f.one(), f.zero(), x1.clone(), f.one());
+    let mut swap = false;
+    for t in (0..255).rev() {
+        let bit = k.bit(t);
+        if swap != bit {
+            core::mem::swap(&mut x2, &mut x3);
+            core::mem::swap(&mut z2, &mut z3);
+        }
+        swap = bit;
+        let a = f.add(&x2, &z2);
+        let aa = f.square(&a);
+        let b = f.sub(&x2, &z2);
+        let bb = f.square(&b);
+        let e = f.sub(&aa, &bb);
+        let c = f.add(&x3, &z3);
+        let d = f.sub(&x3, &z3);
+        let da = f.mul(&d, &a);
+        let cb = f.mul(&c, &b);
+        x3 = f.square(&f.add(&da, &cb));
+        z3 = f.mul(&x1, &f.square(&f.sub(&da, &cb)));
+        x2 = f.mul(&aa, &bb);
+        z2 = f.mul(&e, &f.add(&aa, &f.mul(&a24, &e)));
+    }
+    if swap {
+        x2 = x3;
+        z2 = z3;
+    }
+    let shared = f.from_mont(&f.mul(&x2, &f.invert(&z2))).to_le_bytes(32)?;
+    if shared.iter().all(|&byte| byte == 0) {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&shared);
+    Some(out)
+}
+
+/// A new X25519 key pair: (private scalar, public u-coordinate)
+pub fn x25519_keypair() -> ([u8; 32], [u8; 32]) {
+    let mut private = [0u8; 32];
+    rng::fill_bytes(&mut private);
+    let mut base = [0u8; 32];
+    base[0] = 9;
+    // Only a zero scalar gives zero, and clamping sets bit 254
+    let public = x25519(&private, &base).unwrap_or_default();
+    (private, public)
+}
+
 pub fn get_asymmetric(algorithm: AsymmetricAlgorithm, _provider: CryptoProvider) -> CryptoResult<Box<dyn AsymmetricCrypto>> {
     match algorithm {
         AsymmetricAlgorithm::RSA2048 => Ok(Box::new(RSA::new(2048))),
         AsymmetricAlgorithm::RSA4096 => Ok(Box::new(RSA::new(4096))),
+        AsymmetricAlgorithm::EcdsaP256 => Ok(Box::new(EcdsaP256::new())),
         AsymmetricAlgorithm::Ed25519 => Ok(Box::new(Ed25519::new())),
         _ => Err(CryptoError::UnsupportedAlgorithm),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,415 @@
+// Multi-precision unsigned integers for the public-key algorithms
+//
+// Values are little-endian vectors of 64-bit limbs. Modular arithmetic goes
+// through a Montgomery context for an odd modulus, which covers RSA moduli and
+// the prime fields and group orders of P-256 and Curve25519. None of this is
+// constant time, so it is used for verification and for signing with keys that
+// never leave the kernel.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigUint {
+    limbs: Vec<u64>,
+}
+
+impl BigUint {
+    pub fn zero() -> Self {
+        Self { limbs: Vec::new() }
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self::from_limbs(vec![value])
+    }
+
+    pub fn from_limbs(limbs: Vec<u64>) -> Self {
+        let mut n = Self { limbs };
+        n.normalize();
+        n
+    }
+
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut limbs = Vec::with_capacity((bytes.len() + 7) / 8);
+        for chunk in bytes.rchunks(8) {
+            let mut word = [0u8; 8];
+            word[8 - chunk.len()..].copy_from_slice(chunk);
+            limbs.push(u64::from_be_bytes(word));
+        }
+        Self::from_limbs(limbs)
+    }
+
+    pub fn from_le_bytes(bytes: &[u8]) -> Self {
+        let mut limbs = Vec::with_capacity((bytes.len() + 7) / 8);
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            limbs.push(u64::from_le_bytes(word));
+        }
+        Self::from_limbs(limbs)
+    }
+
+    /// Big-endian encoding left-padded to `len` bytes; `None` if it does not fit
+    pub fn to_be_bytes(&self, len: usize) -> Option<Vec<u8>> {
+        let mut le = self.to_le_bytes(len)?;
+        le.reverse();
+        Some(le)
+    }
+
+    pub fn to_le_bytes(&self, len: usize) -> Option<Vec<u8>> {
+        if self.bits() > len * 8 {
+            return None;
+        }
+        let mut bytes: Vec<u8> = self.limbs.iter().flat_map(|l| l.to_le_bytes()).collect();
+        bytes.resize(len, 0);
+        Some(bytes)
+    }
+
+    fn normalize(&mut self) {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn bits(&self) -> usize {
+        match self.limbs.last() {
+            Some(top) => self.limbs.len() * 64 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        self.limbs
+            .get(index / 64)
+            .map_or(false, |limb| (limb >> (index % 64)) & 1 == 1)
+    }
+
+    pub fn is_odd(&self) -> bool {
+        self.bit(0)
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        let len = self.limbs.len().max(other.limbs.len());
+        let mut result = Vec::with_capacity(len + 1);
+        let mut carry = 0u64;
+        for i in 0..len {
+            let a = self.limbs.get(i).copied().unwrap_or(0);
+            let b = other.limbs.get(i).copied().unwrap_or(0);
+            let (sum, c1) = a.overflowing_add(b);
+            let (sum, c2) = sum.overflowing_add(carry);
+            result.push(sum);
+            carry = (c1 as u64) + (c2 as u64);
+        }
+        result.push(carry);
+        Self::from_limbs(result)
+    }
+
+    /// `self - other`; the caller guarantees `self >= other`
+    pub fn sub(&self, other: &Self) -> Self {
+        let mut result = self.limbs.clone();
+        sub_in_place(&mut result, &other.limbs);
+        Self::from_limbs(result)
+    }
+
+    pub fn mul(&self, other: &Self) -> Self {
+        if self.is_zero() || other.is_zero() {
+            return Self::zero();
+        }
+        let mut result = vec![0u64; self.limbs.len() + other.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let t = result[i + j] as u128 + a as u128 * b as u128 + carry;
+                result[i + j] = t as u64;
+                carry = t >> 64;
+            }
+            result[i + other.limbs.len()] = carry as u64;
+        }
+        Self::from_limbs(result)
+    }
+
+    /// Quotient and remainder by a single word
+    pub fn div_rem_u64(&self, divisor: u64) -> (Self, u64) {
+        let mut quotient = vec![0u64; self.limbs.len()];
+        let mut remainder = 0u128;
+        for i in (0..self.limbs.len()).rev() {
+            let current = (remainder << 64) | self.limbs[i] as u128;
+            quotient[i] = (current / divisor as u128) as u64;
+            remainder = current % divisor as u128;
+        }
+        (Self::from_limbs(quotient), remainder as u64)
+    }
+
+    pub fn shr(&self, bits: usize) -> Self {
+        let words = bits / 64;
+        let shift = bits % 64;
+        if words >= self.limbs.len() {
+            return Self::zero();
+        }
+        let mut limbs = self.limbs[words..].to_vec();
+        if shift != 0 {
+            for i in 0..limbs.len() {
+                let high = limbs.get(i + 1).copied().unwrap_or(0);
+                limbs[i] = (limbs[i] >> shift) | (high << (64 - shift));
+            }
+        }
+        Self::from_limbs(limbs)
+    }
+
+    /// `self mod modulus` by binary long division
+    pub fn rem(&self, modulus: &Self) -> Self {
+        if self < modulus {
+            return self.clone();
+        }
+        let mut remainder = vec![0u64; modulus.limbs.len() + 1];
+        for i in (0..self.bits()).rev() {
+            shl1_in_place(&mut remainder, self.bit(i));
+            if cmp_limbs(&remainder, &modulus.limbs) != Ordering::Less {
+                sub_in_place(&mut remainder, &modulus.limbs);
+            }
+        }
+        Self::from_limbs(remainder)
+    }
+
+    /// `(self + other) mod modulus` for reduced inputs
+    pub fn add_mod(&self, other: &Self, modulus: &Self) -> Self {
+        let sum = self.add(other);
+        if &sum >= modulus {
+            sum.sub(modulus)
+        } else {
+            sum
+        }
+    }
+
+    pub fn mul_mod(&self, other: &Self, modulus: &Self) -> Self {
+        self.mul(other).rem(modulus)
+    }
+}
+
+impl PartialOrd for BigUint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigUint {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_limbs(&self.limbs, &other.limbs)
+    }
+}
+
+// Compare limb slices of possibly different lengths
+fn cmp_limbs(a: &[u64], b: &[u64]) -> Ordering {
+    let len = a.len().max(b.len());
+    for i in (0..len).rev() {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        if x != y {
+            return x.cmp(&y);
+        }
+    }
+    Ordering::Equal
+}
+
+// a -= b, returning the borrow out of the top limb
+fn sub_in_place(a: &mut [u64], b: &[u64]) -> bool {
+    let mut borrow = false;
+    for i in 0..a.len() {
+        let (d, b1) = a[i].overflowing_sub(b.get(i).copied().unwrap_or(0));
+        let (d, b2) = d.overflowing_sub(borrow as u64);
+        a[i] = d;
+        borrow = b1 || b2;
+    }
+    borrow
+}
+
+fn shl1_in_place(a: &mut [u64], low_bit: bool) {
+    let mut carry = low_bit as u64;
+    for limb in a.iter_mut() {
+        let next = *limb >> 63;
+        *limb = (*limb << 1) | carry;
+        carry = next;
+    }
+}
+
+// Arithmetic modulo a fixed odd modulus on Montgomery residues of exactly
+// `limbs` words
+pub struct Montgomery {
+    modulus: BigUint,
+    n: Vec<u64>,
+    n0_inv: u64,
+    r2: Vec<u64>,
+    one: Vec<u64>,
+}
+
+impl Montgomery {
+    pub fn new(modulus: &BigUint) -> Option<Self> {
+        if !modulus.is_odd() || modulus.bits() < 2 {
+            return None;
+        }
+        let k = modulus.limbs.len();
+        let n = modulus.limbs.clone();
+
+        // -n^-1 mod 2^64 by Newton iteration
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inv)));
+        }
+
+        let mut r_limbs = vec![0u64; k];
+        r_limbs.push(1);
+        let r = BigUint::from_limbs(r_limbs).rem(modulus);
+        let r2 = r.mul(&r).rem(modulus);
+
+        Some(Self {
+            modulus: modulus.clone(),
+            n0_inv: inv.wrapping_neg(),
+            r2: pad(&r2.limbs, k),
+            one: pad(&r.limbs, k),
+            n,
+        })
+    }
+
+    pub fn modulus(&self) -> &BigUint {
+        &self.modulus
+    }
+
+    fn k(&self) -> usize {
+        self.n.len()
+    }
+
+    /// Montgomery product a * b / R mod n (CIOS)
+    pub fn mul(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        let k = self.k();
+        let mut t = vec![0u64; k + 2];
+        for i in 0..k {
+            let mut carry = 0u128;
+            for j in 0..k {
+                let s = t[j] as u128 + a[j] as u128 * b[i] as u128 + carry;
+                t[j] = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[k] as u128 + carry;
+            t[k] = s as u64;
+            t[k + 1] = (s >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.n0_inv);
+            let s = t[0] as u128 + m as u128 * self.n[0] as u128;
+            let mut carry = s >> 64;
+            for j in 1..k {
+                let s = t[j] as u128 + m as u128 * self.n[j] as u128 + carry;
+                t[j - 1] = s as u64;
+                carry = s >> 64;
+            }
+            let s = t[k] as u128 + carry;
+            t[k - 1] = s as u64;
+            t[k] = t[k + 1] + (s >> 64) as u64;
+            t[k + 1] = 0;
+        }
+
+        t.truncate(k + 1);
+        if t[k] != 0 || cmp_limbs(&t[..k], &self.n) != Ordering::Less {
+            sub_in_place(&mut t, &self.n);
+        }
+        t.truncate(k);
+        t
+    }
+
+    pub fn square(&self, a: &[u64]) -> Vec<u64> {
+        self.mul(a, a)
+    }
+
+    pub fn add(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        let mut sum = a.to_vec();
+        sum.push(0);
+        let mut carry = false;
+        for i in 0..self.k() {
+            let (s, c1) = sum[i].overflowing_add(b[i]);
+            let (s, c2) = s.overflowing_add(carry as u64);
+            sum[i] = s;
+            carry = c1 || c2;
+        }
+        sum[self.k()] = carry as u64;
+        if cmp_limbs(&sum, &self.n) != Ordering::Less {
+            sub_in_place(&mut sum, &self.n);
+        }
+        sum.truncate(self.k());
+        sum
+    }
+
+    pub fn sub(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        let mut diff = a.to_vec();
+        if sub_in_place(&mut diff, b) {
+            // Wrapped below zero: add n back
+            let mut carry = false;
+            for i in 0..self.k() {
+                let (s, c1) = diff[i].overflowing_add(self.n[i]);
+                let (s, c2) = s.overflowing_add(carry as u64);
+                diff[i] = s;
+                carry = c1 || c2;
+            }
+        }
+        diff
+    }
+
+    pub fn neg(&self, a: &[u64]) -> Vec<u64> {
+        self.sub(&self.zero(), a)
+    }
+
+    pub fn zero(&self) -> Vec<u64> {
+        vec![0u64; self.k()]
+    }
+
+    pub fn one(&self) -> Vec<u64> {
+        self.one.clone()
+    }
+
+    pub fn is_zero(&self, a: &[u64]) -> bool {
+        a.iter().all(|&limb| limb == 0)
+    }
+
+    pub fn to_mont(&self, x: &BigUint) -> Vec<u64> {
+        let reduced = x.rem(&self.modulus);
+        self.mul(&pad(&reduced.limbs, self.k()), &self.r2)
+    }
+
+    pub fn from_mont(&self, a: &[u64]) -> BigUint {
+        let mut one = vec![0u64; self.k()];
+        one[0] = 1;
+        BigUint::from_limbs(self.mul(a, &one))
+    }
+
+    /// base^exp on a Montgomery residue, left-to-right square and multiply
+    pub fn pow(&self, base: &[u64], exp: &BigUint) -> Vec<u64> {
+        let mut result = self.one();
+        for i in (0..exp.bits()).rev() {
+            result = self.square(&result);
+            if exp.bit(i) {
+                result = self.mul(&result, base);
+            }
+        }
+        result
+    }
+
+    /// Inverse modulo a prime modulus by Fermat's little theorem
+    pub fn invert(&self, a: &[u64]) -> Vec<u64> {
+        let exp = self.modulus.sub(&BigUint::from_u64(2));
+        self.pow(a, &exp)
+    }
+
+    /// Plain (non-Montgomery) base^exp mod n
+    pub fn mod_pow(&self, base: &BigUint, exp: &BigUint) -> BigUint {
+        self.from_mont(&self.pow(&self.to_mont(base), exp))
+    }
+}
+
+fn pad(limbs: &[u64], len: usize) -> Vec<u64> {
+    let mut padded = limbs.to_vec();
+    padded.resize(len, 0);
+    padded
+}
@@ -1,8 +1,7 @@
-#![no_std]
-
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::convert::TryInto;
 use super::errors::{CryptoError, CryptoResult};
+use super::hw_accel;
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,6 +9,8 @@ pub enum CipherAlgorithm {
     Aes128,
     Aes192,
     Aes256,
+    Aes128Xts,
+    Aes256Xts,
     ChaCha20,
     TripleDes,
     Blowfish,
@@ -36,324 +37,368 @@ pub trait SymmetricCipher: Send + Sync {
     fn iv_size(&self) -> Option<usize>;
 }
 
-pub struct AesCipher {
-    key_size: usize,
-    mode: CipherMode,
+// Expanded AES key, on AES-NI when the CPU has it
+#[derive(Clone)]
+pub struct AesKey {
+    rounds: usize,
+    round_keys: Vec<u32>,
+    #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+    ni: Option<hw_accel::aes_ni::AesNiKey>,
 }
 
-impl AesCipher {
-    pub fn new(key_size: usize, mode: CipherMode) -> Self {
-        Self { key_size, mode }
+impl AesKey {
+    pub fn new(key: &[u8]) -> CryptoResult<Self> {
+        #[allow(unused_mut)]
+        let mut aes_key = Self::new_software(key)?;
+        #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+        if hw_accel::use_aes_ni() {
+            aes_key.ni = unsafe { hw_accel::aes_ni::AesNiKey::new(key) };
+        }
+        Ok(aes_key)
     }
-    
-    fn aes_encrypt_block(&self, block: &[u8], round_keys: &[u32]) -> [u8; 16] {
-        let mut state = [0u8; 16];
-        state.copy_from_slice(block);
-        
-        let nr = match self.key_size {
+
+    pub fn new_software(key: &[u8]) -> CryptoResult<Self> {
+        let rounds = match key.len() {
             16 => 10,
             24 => 12,
             32 => 14,
-            _ => panic!("Invalid AES key size"),
+            _ => return Err(CryptoError::InvalidKeySize),
         };
-        
-        self.add_round_key(&mut state, &round_keys[0..4]);
-        
+        Ok(Self {
+            rounds,
+            round_keys: key_expansion(key, rounds),
+            #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+            ni: None,
+        })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+        if let Some(ni) = &self.ni {
+            unsafe { ni.encrypt_block(block) };
+            return;
+        }
+
+        let nr = self.rounds;
+        let rk = &self.round_keys;
+        add_round_key(block, &rk[0..4]);
         for round in 1..nr {
-            self.sub_bytes(&mut state);
-            self.shift_rows(&mut state);
-            self.mix_columns(&mut state);
-            self.add_round_key(&mut state, &round_keys[round * 4..(round + 1) * 4]);
+            sub_bytes(block);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &rk[round * 4..(round + 1) * 4]);
         }
-        
-        self.sub_bytes(&mut state);
-        self.shift_rows(&mut state);
-        self.add_round_key(&mut state, &round_keys[nr * 4..(nr + 1) * 4]);
-        
-        state
+        sub_bytes(block);
+        shift_rows(block);
+        add_round_key(block, &rk[nr * 4..(nr + 1) * 4]);
     }
-    
-    fn aes_decrypt_block(&self, block: &[u8], round_keys: &[u32]) -> [u8; 16] {
-        let mut state = [0u8; 16];
-        state.copy_from_slice(block);
-        
-        let nr = match self.key_size {
-            16 => 10,
-            24 => 12,
-            32 => 14,
-            _ => panic!("Invalid AES key size"),
-        };
-        
-        self.add_round_key(&mut state, &round_keys[nr * 4..(nr + 1) * 4]);
-        
+
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+        if let Some(ni) = &self.ni {
+            unsafe { ni.decrypt_block(block) };
+            return;
+        }
+
+        let nr = self.rounds;
+        let rk = &self.round_keys;
+        add_round_key(block, &rk[nr * 4..(nr + 1) * 4]);
         for round in (1..nr).rev() {
-            self.inv_shift_rows(&mut state);
-            self.inv_sub_bytes(&mut state);
-            self.add_round_key(&mut state, &round_keys[round * 4..(round + 1) * 4]);
-            self.inv_mix_columns(&mut state);
+            inv_shift_rows(block);
+            inv_sub_bytes(block);
+            add_round_key(block, &rk[round * 4..(round + 1) * 4]);
+            inv_mix_columns(block);
         }
-        
-        self.inv_shift_rows(&mut state);
-        self.inv_sub_bytes(&mut state);
-        self.add_round_key(&mut state, &round_keys[0..4]);
-        
-        state
+        inv_shift_rows(block);
+        inv_sub_bytes(block);
+        add_round_key(block, &rk[0..4]);
     }
-    
-    fn key_expansion(&self, key: &[u8]) -> Vec<u32> {
-        let nk = self.key_size / 4;
-        let nr = match self.key_size {
-            16 => 10,
-            24 => 12,
-            32 => 14,
-            _ => panic!("Invalid AES key size"),
-        };
-        
-        let mut w = Vec::with_capacity(4 * (nr + 1));
-        
-        for i in 0..nk {
-            w.push(u32::from_be_bytes([
-                key[4 * i],
-                key[4 * i + 1],
-                key[4 * i + 2],
-                key[4 * i + 3],
-            ]));
-        }
-        
-        for i in nk..(4 * (nr + 1)) {
-            let mut temp = w[i - 1];
-            if i % nk == 0 {
-                temp = self.sub_word(self.rot_word(temp)) ^ self.rcon(i / nk);
-            } else if nk > 6 && i % nk == 4 {
-                temp = self.sub_word(temp);
-            }
-            w.push(w[i - nk] ^ temp);
-        }
-        
-        w
+}
+
+pub struct AesCipher {
+    key_size: usize,
+    mode: CipherMode,
+}
+
+impl AesCipher {
+    pub fn new(key_size: usize, mode: CipherMode) -> Self {
+        Self { key_size, mode }
     }
-    
-    fn add_round_key(&self, state: &mut [u8; 16], round_key: &[u32]) {
-        for i in 0..4 {
-            let key_bytes = round_key[i].to_be_bytes();
-            for j in 0..4 {
-                state[i * 4 + j] ^= key_bytes[j];
-            }
+
+    // XTS takes the data key and the tweak key concatenated
+    fn expected_key_len(&self) -> usize {
+        match self.mode {
+            CipherMode::XTS => self.key_size * 2,
+            _ => self.key_size,
         }
     }
-    
-    fn sub_bytes(&self, state: &mut [u8; 16]) {
-        for byte in state.iter_mut() {
-            *byte = AES_SBOX[*byte as usize];
-        }
+
+    fn block_iv(iv: Option<&[u8]>) -> CryptoResult<[u8; 16]> {
+        let iv = iv.ok_or(CryptoError::InvalidNonce)?;
+        iv.try_into().map_err(|_| CryptoError::InvalidNonce)
     }
-    
-    fn inv_sub_bytes(&self, state: &mut [u8; 16]) {
-        for byte in state.iter_mut() {
-            *byte = AES_INV_SBOX[*byte as usize];
-        }
+}
+
+// Counter mode keystream; the whole 16-byte block is a big-endian counter
+fn ctr_apply(key: &AesKey, counter: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let mut counter = u128::from_be_bytes(*counter);
+    let mut output = Vec::with_capacity(data.len());
+    for chunk in data.chunks(16) {
+        let mut keystream = counter.to_be_bytes();
+        key.encrypt_block(&mut keystream);
+        output.extend(chunk.iter().zip(keystream.iter()).map(|(d, k)| d ^ k));
+        counter = counter.wrapping_add(1);
+    }
+    output
+}
+
+// Multiply an XTS tweak by the primitive element of GF(2^128)
+fn xts_next_tweak(tweak: &mut [u8; 16]) {
+    let carry = tweak[15] >> 7;
+    for i in (1..16).rev() {
+        tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
     }
-    
-    fn shift_rows(&self, state: &mut [u8; 16]) {
-        let temp = state[1];
-        state[1] = state[5];
-        state[5] = state[9];
-        state[9] = state[13];
-        state[13] = temp;
-        
-        let temp = state[2];
-        state[2] = state[10];
-        state[10] = temp;
-        let temp = state[6];
-        state[6] = state[14];
-        state[14] = temp;
-        
-        let temp = state[3];
-        state[3] = state[15];
-        state[15] = state[11];
-        state[11] = state[7];
-        state[7] = temp;
+    tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+}
+
+fn xts_block(block: &mut [u8; 16], tweak: &[u8; 16], key: &AesKey, encrypt: bool) {
+    for (b, t) in block.iter_mut().zip(tweak.iter()) {
+        *b ^= t;
     }
-    
-    fn inv_shift_rows(&self, state: &mut [u8; 16]) {
-        let temp = state[13];
-        state[13] = state[9];
-        state[9] = state[5];
-        state[5] = state[1];
-        state[1] = temp;
-        
-        let temp = state[2];
-        state[2] = state[10];
-        state[10] = temp;
-        let temp = state[6];
-        state[6] = state[14];
-        state[14] = temp;
-        
-        let temp = state[7];
-        state[7] = state[11];
-        state[11] = state[15];
-        state[15] = state[3];
-        state[3] = temp;
+    if encrypt {
+        key.encrypt_block(block);
+    } else {
+        key.decrypt_block(block);
     }
-    
-    fn mix_columns(&self, state: &mut [u8; 16]) {
-        for i in 0..4 {
-            let col = [
-                state[i * 4],
-                state[i * 4 + 1],
-                state[i * 4 + 2],
-                state[i * 4 + 3],
-            ];
-            
-            state[i * 4] = gmul(0x02, col[0]) ^ gmul(0x03, col[1]) ^ col[2] ^ col[3];
-            state[i * 4 + 1] = col[0] ^ gmul(0x02, col[1]) ^ gmul(0x03, col[2]) ^ col[3];
-            state[i * 4 + 2] = col[0] ^ col[1] ^ gmul(0x02, col[2]) ^ gmul(0x03, col[3]);
-            state[i * 4 + 3] = gmul(0x03, col[0]) ^ col[1] ^ col[2] ^ gmul(0x02, col[3]);
-        }
+    for (b, t) in block.iter_mut().zip(tweak.iter()) {
+        *b ^= t;
     }
-    
-    fn inv_mix_columns(&self, state: &mut [u8; 16]) {
-        for i in 0..4 {
-            let col = [
-                state[i * 4],
-                state[i * 4 + 1],
-                state[i * 4 + 2],
-                state[i * 4 + 3],
-            ];
-            
-            state[i * 4] = gmul(0x0e, col[0]) ^ gmul(0x0b, col[1]) ^ gmul(0x0d, col[2]) ^ gmul(0x09, col[3]);
-            state[i * 4 + 1] = gmul(0x09, col[0]) ^ gmul(0x0e, col[1]) ^ gmul(0x0b, col[2]) ^ gmul(0x0d, col[3]);
-            state[i * 4 + 2] = gmul(0x0d, col[0]) ^ gmul(0x09, col[1]) ^ gmul(0x0e, col[2]) ^ gmul(0x0b, col[3]);
-            state[i * 4 + 3] = gmul(0x0b, col[0]) ^ gmul(0x0d, col[1]) ^ gmul(0x09, col[2]) ^ gmul(0x0e, col[3]);
-        }
+}
+
+/// IEEE 1619 XTS with ciphertext stealing for a trailing partial block.
+/// `key` is the data key followed by the tweak key.
+pub fn xts_process(key: &[u8], tweak: &[u8; 16], data: &[u8], encrypt: bool) -> CryptoResult<Vec<u8>> {
+    if key.len() != 32 && key.len() != 64 {
+        return Err(CryptoError::InvalidKeySize);
     }
-    
-    fn rot_word(&self, word: u32) -> u32 {
-        (word << 8) | (word >> 24)
+    if data.len() < 16 {
+        return Err(CryptoError::InvalidBlockSize);
     }
-    
-    fn sub_word(&self, word: u32) -> u32 {
-        let bytes = word.to_be_bytes();
-        u32::from_be_bytes([
-            AES_SBOX[bytes[0] as usize],
-            AES_SBOX[bytes[1] as usize],
-            AES_SBOX[bytes[2] as usize],
-            AES_SBOX[bytes[3] as usize],
-        ])
+
+    let (data_key, tweak_key) = key.split_at(key.len() / 2);
+    let data_key = AesKey::new(data_key)?;
+    let mut t = *tweak;
+    AesKey::new(tweak_key)?.encrypt_block(&mut t);
+
+    let partial = data.len() % 16;
+    let full_blocks = data.len() / 16 - if partial != 0 { 1 } else { 0 };
+    let mut output = Vec::with_capacity(data.len());
+
+    for chunk in data[..full_blocks * 16].chunks_exact(16) {
+        let mut block: [u8; 16] = chunk.try_into().unwrap();
+        xts_block(&mut block, &t, &data_key, encrypt);
+        output.extend_from_slice(&block);
+        xts_next_tweak(&mut t);
     }
-    
-    fn rcon(&self, i: usize) -> u32 {
-        RCON[i - 1]
+
+    if partial != 0 {
+        let last_full: [u8; 16] = data[full_blocks * 16..full_blocks * 16 + 16].try_into().unwrap();
+        let tail = &data[full_blocks * 16 + 16..];
+        let mut t_next = t;
+        xts_next_tweak(&mut t_next);
+
+        // Decryption consumes the two tweaks in the opposite order
+        let (first, second) = if encrypt { (t, t_next) } else { (t_next, t) };
+        let mut stolen = last_full;
+        xts_block(&mut stolen, &first, &data_key, encrypt);
+
+        let mut block = stolen;
+        block[..partial].copy_from_slice(tail);
+        xts_block(&mut block, &second, &data_key, encrypt);
+
+        output.extend_from_slice(&block);
+        output.extend_from_slice(&stolen[..partial]);
     }
+
+    Ok(output)
+}
+
+// RFC 3394's default initial value
+const KEY_WRAP_IV: [u8; 8] = [0xA6; 8];
+
+/// RFC 3394 AES key wrap of `data`, a whole number of 64-bit blocks and
+/// at least two
+pub fn aes_key_wrap(kek: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
+    if data.len() % 8 != 0 || data.len() < 16 {
+        return Err(CryptoError::InvalidBlockSize);
+    }
+    let aes = AesKey::new(kek)?;
+    let n = data.len() / 8;
+    let mut a = KEY_WRAP_IV;
+    let mut r = data.to_vec();
+
+    for j in 0..6 {
+        for i in 0..n {
+            let mut block = [0u8; 16];
+            block[..8].copy_from_slice(&a);
+            block[8..].copy_from_slice(&r[i * 8..i * 8 + 8]);
+            aes.encrypt_block(&mut block);
+            let t = (n * j + i + 1) as u64;
+            for (byte, (b, t)) in a.iter_mut().zip(block[..8].iter().zip(t.to_be_bytes())) {
+                *byte = b ^ t;
+            }
+            r[i * 8..i * 8 + 8].copy_from_slice(&block[8..]);
+        }
+    }
+
+    let mut output = a.to_vec();
+    output.extend_from_slice(&r);
+    Ok(output)
+}
+
+/// Undo `aes_key_wrap`, failing if the initial value does not come back
+pub fn aes_key_unwrap(kek: &[u8], wrapped: &[u8]) -> CryptoResult<Vec<u8>> {
+    if wrapped.len() % 8 != 0 || wrapped.len() < 24 {
+        return Err(CryptoError::InvalidBlockSize);
+    }
+    let aes = AesKey::new(kek)?;
+    let n = wrapped.len() / 8 - 1;
+    let mut a: [u8; 8] = wrapped[..8].try_into().unwrap();
+    let mut r = wrapped[8..].to_vec();
+
+    for j in (0..6).rev() {
+        for i in (0..n).rev() {
+            let t = (n * j + i + 1) as u64;
+            let mut block = [0u8; 16];
+            for (byte, (a, t)) in block[..8].iter_mut().zip(a.iter().zip(t.to_be_bytes())) {
+                *byte = a ^ t;
+            }
+            block[8..].copy_from_slice(&r[i * 8..i * 8 + 8]);
+            aes.decrypt_block(&mut block);
+            a.copy_from_slice(&block[..8]);
+            r[i * 8..i * 8 + 8].copy_from_slice(&block[8..]);
+        }
+    }
+
+    if a.iter().zip(KEY_WRAP_IV.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) != 0 {
+        return Err(CryptoError::AuthenticationFailed);
+    }
+    Ok(r)
 }
 
 impl SymmetricCipher for AesCipher {
     fn encrypt(&self, plaintext: &[u8], key: &[u8], iv: Option<&[u8]>) -> CryptoResult<Vec<u8>> {
-        if key.len() != self.key_size {
+        if key.len() != self.expected_key_len() {
             return Err(CryptoError::InvalidKeySize);
         }
-        
-        let round_keys = self.key_expansion(key);
+
         let mut ciphertext = Vec::new();
-        
+
         match self.mode {
             CipherMode::ECB => {
+                let round_keys = AesKey::new(key)?;
                 for chunk in plaintext.chunks(16) {
                     let mut block = [0u8; 16];
                     block[..chunk.len()].copy_from_slice(chunk);
                     if chunk.len() < 16 {
                         apply_pkcs7_padding(&mut block, chunk.len());
                     }
-                    let encrypted = self.aes_encrypt_block(&block, &round_keys);
-                    ciphertext.extend_from_slice(&encrypted);
+                    round_keys.encrypt_block(&mut block);
+                    ciphertext.extend_from_slice(&block);
                 }
             }
             CipherMode::CBC => {
-                let iv = iv.ok_or(CryptoError::InvalidNonce)?;
-                if iv.len() != 16 {
-                    return Err(CryptoError::InvalidNonce);
-                }
-                
-                let mut prev_block = [0u8; 16];
-                prev_block.copy_from_slice(iv);
-                
+                let round_keys = AesKey::new(key)?;
+                let mut prev_block = Self::block_iv(iv)?;
+
                 for chunk in plaintext.chunks(16) {
                     let mut block = [0u8; 16];
                     block[..chunk.len()].copy_from_slice(chunk);
                     if chunk.len() < 16 {
                         apply_pkcs7_padding(&mut block, chunk.len());
                     }
-                    
+
                     for i in 0..16 {
                         block[i] ^= prev_block[i];
                     }
-                    
-                    let encrypted = self.aes_encrypt_block(&block, &round_keys);
-                    ciphertext.extend_from_slice(&encrypted);
-                    prev_block = encrypted;
+
+                    round_keys.encrypt_block(&mut block);
+                    ciphertext.extend_from_slice(&block);
+                    prev_block = block;
                 }
             }
+            CipherMode::CTR => {
+                let round_keys = AesKey::new(key)?;
+                ciphertext = ctr_apply(&round_keys, &Self::block_iv(iv)?, plaintext);
+            }
+            CipherMode::XTS => {
+                ciphertext = xts_process(key, &Self::block_iv(iv)?, plaintext, true)?;
+            }
             _ => return Err(CryptoError::UnsupportedAlgorithm),
         }
-        
+
         Ok(ciphertext)
     }
-    
+
     fn decrypt(&self, ciphertext: &[u8], key: &[u8], iv: Option<&[u8]>) -> CryptoResult<Vec<u8>> {
-        if key.len() != self.key_size {
+        if key.len() != self.expected_key_len() {
             return Err(CryptoError::InvalidKeySize);
         }
-        
+
+        match self.mode {
+            CipherMode::CTR => {
+                let round_keys = AesKey::new(key)?;
+                return Ok(ctr_apply(&round_keys, &Self::block_iv(iv)?, ciphertext));
+            }
+            CipherMode::XTS => return xts_process(key, &Self::block_iv(iv)?, ciphertext, false),
+            _ => {}
+        }
+
         if ciphertext.len() % 16 != 0 {
             return Err(CryptoError::InvalidBlockSize);
         }
-        
-        let round_keys = self.key_expansion(key);
+
+        let round_keys = AesKey::new(key)?;
         let mut plaintext = Vec::new();
-        
+
         match self.mode {
             CipherMode::ECB => {
                 for chunk in ciphertext.chunks(16) {
-                    let decrypted = self.aes_decrypt_block(chunk, &round_keys);
-                    plaintext.extend_from_slice(&decrypted);
+                    let mut block: [u8; 16] = chunk.try_into().unwrap();
+                    round_keys.decrypt_block(&mut block);
+                    plaintext.extend_from_slice(&block);
                 }
             }
             CipherMode::CBC => {
-                let iv = iv.ok_or(CryptoError::InvalidNonce)?;
-                if iv.len() != 16 {
-                    return Err(CryptoError::InvalidNonce);
-                }
-                
-                let mut prev_block = [0u8; 16];
-                prev_block.copy_from_slice(iv);
-                
+                let mut prev_block = Self::block_iv(iv)?;
+
                 for chunk in ciphertext.chunks(16) {
-                    let decrypted = self.aes_decrypt_block(chunk, &round_keys);
-                    
-                    let mut block = [0u8; 16];
+                    let mut block: [u8; 16] = chunk.try_into().unwrap();
+                    round_keys.decrypt_block(&mut block);
+
                     for i in 0..16 {
-                        block[i] = decrypted[i] ^ prev_block[i];
+                        block[i] ^= prev_block[i];
                     }
-                    
+
                     plaintext.extend_from_slice(&block);
                     prev_block.copy_from_slice(chunk);
                 }
             }
             _ => return Err(CryptoError::UnsupportedAlgorithm),
         }
-        
+
         remove_pkcs7_padding(&mut plaintext)?;
         Ok(plaintext)
     }
-    
+
     fn block_size(&self) -> usize {
         16
     }
-    
+
     fn key_size(&self) -> usize {
-        self.key_size
+        self.expected_key_len()
     }
-    
+
     fn iv_size(&self) -> Option<usize> {
         match self.mode {
             CipherMode::ECB => None,
@@ -362,6 +407,119 @@ impl SymmetricCipher for AesCipher {
     }
 }
 
+fn key_expansion(key: &[u8], nr: usize) -> Vec<u32> {
+    let nk = key.len() / 4;
+    let mut w = Vec::with_capacity(4 * (nr + 1));
+
+    for i in 0..nk {
+        w.push(u32::from_be_bytes([
+            key[4 * i],
+            key[4 * i + 1],
+            key[4 * i + 2],
+            key[4 * i + 3],
+        ]));
+    }
+
+    for i in nk..(4 * (nr + 1)) {
+        let mut temp = w[i - 1];
+        if i % nk == 0 {
+            temp = sub_word(temp.rotate_left(8)) ^ RCON[i / nk - 1];
+        } else if nk > 6 && i % nk == 4 {
+            temp = sub_word(temp);
+        }
+        w.push(w[i - nk] ^ temp);
+    }
+
+    w
+}
+
+fn add_round_key(state: &mut [u8; 16], round_key: &[u32]) {
+    for i in 0..4 {
+        let key_bytes = round_key[i].to_be_bytes();
+        for j in 0..4 {
+            state[i * 4 + j] ^= key_bytes[j];
+        }
+    }
+}
+
+fn sub_bytes(state: &mut [u8; 16]) {
+    for byte in state.iter_mut() {
+        *byte = AES_SBOX[*byte as usize];
+    }
+}
+
+fn inv_sub_bytes(state: &mut [u8; 16]) {
+    for byte in state.iter_mut() {
+        *byte = AES_INV_SBOX[*byte as usize];
+    }
+}
+
+fn shift_rows(state: &mut [u8; 16]) {
+    let temp = state[1];
+    state[1] = state[5];
+    state[5] = state[9];
+    state[9] = state[13];
+    state[13] = temp;
+
+    state.swap(2, 10);
+    state.swap(6, 14);
+
+    let temp = state[3];
+    state[3] = state[15];
+    state[15] = state[11];
+    state[11] = state[7];
+    state[7] = temp;
+}
+
+fn inv_shift_rows(state: &mut [u8; 16]) {
+    let temp = state[13];
+    state[13] = state[9];
+    state[9] = state[5];
+    state[5] = state[1];
+    state[1] = temp;
+
+    state.swap(2, 10);
+    state.swap(6, 14);
+
+    let temp = state[7];
+    state[7] = state[11];
+    state[11] = state[15];
+    state[15] = state[3];
+    state[3] = temp;
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    for i in 0..4 {
+        let col = [state[i * 4], state[i * 4 + 1], state[i * 4 + 2], state[i * 4 + 3]];
+
+        state[i * 4] = gmul(0x02, col[0]) ^ gmul(0x03, col[1]) ^ col[2] ^ col[3];
+        state[i * 4 + 1] = col[0] ^ gmul(0x02, col[1]) ^ gmul(0x03, col[2]) ^ col[3];
+        state[i * 4 + 2] = col[0] ^ col[1] ^ gmul(0x02, col[2]) ^ gmul(0x03, col[3]);
+        state[i * 4 + 3] = gmul(0x03, col[0]) ^ col[1] ^ col[2] ^ gmul(0x02, col[3]);
+    }
+}
+
+fn inv_mix_columns(state: &mut [u8; 16]) {
+    for i in 0..4 {
+        let col = [state[i * 4], state[i * 4 + 1], state[i * 4 + 2], state[i * 4 + 3]];
+
+        state[i * 4] = gmul(0x0e, col[0]) ^ gmul(0x0b, col[1]) ^ gmul(0x0d, col[2]) ^ gmul(0x09, col[3]);
+        state[i * 4 + 1] = gmul(0x09, col[0]) ^ gmul(0x0e, col[1]) ^ gmul(0x0b, col[2]) ^ gmul(0x0d, col[3]);
+        state[i * 4 + 2] = gmul(0x0d, col[0]) ^ gmul(0x09, col[1]) ^ gmul(0x0e, col[2]) ^ gmul(0x0b, col[3]);
+        state[i * 4 + 3] = gmul(0x0b, col[0]) ^ gmul(0x0d, col[1]) ^ gmul(0x09, col[2]) ^ gmul(0x0e, col[3]);
+    }
+}
+
+fn sub_word(word: u32) -> u32 {
+    let bytes = word.to_be_bytes();
+    u32::from_be_bytes([
+        AES_SBOX[bytes[0] as usize],
+        AES_SBOX[bytes[1] as usize],
+        AES_SBOX[bytes[2] as usize],
+        AES_SBOX[bytes[3] as usize],
+    ])
+}
+
 pub struct ChaCha20Cipher;
 
 impl ChaCha20Cipher {
@@ -369,22 +527,18 @@ impl ChaCha20Cipher {
         Self
     }
     
-    fn quarter_round(&self, a: &mut u32, b: &mut u32, c: &mut u32, d: &mut u32) {
-        *a = a.wrapping_add(*b);
-        *d ^= *a;
-        *d = d.rotate_left(16);
-        
-        *c = c.wrapping_add(*d);
-        *b ^= *c;
-        *b = b.rotate_left(12);
-        
-        *a = a.wrapping_add(*b);
-        *d ^= *a;
-        *d = d.rotate_left(8);
-        
-        *c = c.wrapping_add(*d);
-        *b ^= *c;
-        *b = b.rotate_left(7);
+    fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(16);
+
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(12);
+
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(8);
+
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(7);
     }
     
     fn chacha20_block(&self, key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; 64] {
@@ -418,15 +572,15 @@ impl ChaCha20Cipher {
         let mut working_state = state;
         
         for _ in 0..10 {
-            self.quarter_round(&mut working_state[0], &mut working_state[4], &mut working_state[8], &mut working_state[12]);
-            self.quarter_round(&mut working_state[1], &mut working_state[5], &mut working_state[9], &mut working_state[13]);
-            self.quarter_round(&mut working_state[2], &mut working_state[6], &mut working_state[10], &mut working_state[14]);
-            self.quarter_round(&mut working_state[3], &mut working_state[7], &mut working_state[11], &mut working_state[15]);
+            Self::quarter_round(&mut working_state, 0, 4, 8, 12);
+            Self::quarter_round(&mut working_state, 1, 5, 9, 13);
+            Self::quarter_round(&mut working_state, 2, 6, 10, 14);
+            Self::quarter_round(&mut working_state, 3, 7, 11, 15);
             
-            self.quarter_round(&mut working_state[0], &mut working_state[5], &mut working_state[10], &mut working_state[15]);
-            self.quarter_round(&mut working_state[1], &mut working_state[6], &mut working_state[11], &mut working_state[12]);
-            self.quarter_round(&mut working_state[2], &mut working_state[7], &mut working_state[8], &mut working_state[13]);
-            self.quarter_round(&mut working_state[3], &mut working_state[4], &mut working_state[9], &mut working_state[14]);
+            Self::quarter_round(&mut working_state, 0, 5, 10, 15);
+            Self::quarter_round(&mut working_state, 1, 6, 11, 12);
+            Self::quarter_round(&mut working_state, 2, 7, 8, 13);
+            Self::quarter_round(&mut working_state, 3, 4, 9, 14);
         }
         
         for i in 0..16 {
@@ -441,6 +595,23 @@ impl ChaCha20Cipher {
         
         output
     }
+    
+    /// XOR `data` with the keystream starting at block `counter`
+    pub fn apply_keystream(&self, key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(data.len());
+        let mut counter = counter;
+
+        for chunk in data.chunks(64) {
+            let keystream = self.chacha20_block(key, nonce, counter);
+            counter = counter.wrapping_add(1);
+
+            for (i, &byte) in chunk.iter().enumerate() {
+                output.push(byte ^ keystream[i]);
+            }
+        }
+
+        output
+    }
 }
 
 impl SymmetricCipher for ChaCha20Cipher {
@@ -454,25 +625,9 @@ impl SymmetricCipher for ChaCha20Cipher {
             return Err(CryptoError::InvalidNonce);
         }
         
-        let mut key_array = [0u8; 32];
-        key_array.copy_from_slice(key);
-        
-        let mut nonce_array = [0u8; 12];
-        nonce_array.copy_from_slice(nonce);
-        
-        let mut ciphertext = Vec::with_capacity(plaintext.len());
-        let mut counter = 0u32;
-        
-        for chunk in plaintext.chunks(64) {
-            let keystream = self.chacha20_block(&key_array, &nonce_array, counter);
-            counter += 1;
-            
-            for (i, &byte) in chunk.iter().enumerate() {
-                ciphertext.push(byte ^ keystream[i]);
-            }
-        }
-        
-        Ok(ciphertext)
+        let key: &[u8; 32] = key.try_into().unwrap();
+        let nonce: &[u8; 12] = nonce.try_into().unwrap();
+        Ok(self.apply_keystream(key, nonce, 0, plaintext))
     }
     
     fn decrypt(&self, ciphertext: &[u8], key: &[u8], iv: Option<&[u8]>) -> CryptoResult<Vec<u8>> {
@@ -545,6 +700,8 @@ pub fn get_cipher(algorithm: CipherAlgorithm, _provider: CryptoProvider) -> Cryp
         CipherAlgorithm::Aes128 => Ok(Box::new(AesCipher::new(16, CipherMode::CBC))),
         CipherAlgorithm::Aes192 => Ok(Box::new(AesCipher::new(24, CipherMode::CBC))),
         CipherAlgorithm::Aes256 => Ok(Box::new(AesCipher::new(32, CipherMode::CBC))),
+        CipherAlgorithm::Aes128Xts => Ok(Box::new(AesCipher::new(16, CipherMode::XTS))),
+        CipherAlgorithm::Aes256Xts => Ok(Box::new(AesCipher::new(32, CipherMode::XTS))),
         CipherAlgorithm::ChaCha20 => Ok(Box::new(ChaCha20Cipher::new())),
         _ => Err(CryptoError::UnsupportedAlgorithm),
     }
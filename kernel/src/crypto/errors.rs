@@ -1,5 +1,3 @@
-#![no_std]
-
 use core::fmt;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -1,13 +1,13 @@
-#![no_std]
-
+use alloc::boxed::Box;
 use alloc::vec::Vec;
-use core::convert::TryInto;
 use super::errors::{CryptoError, CryptoResult};
+use super::hw_accel;
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HashAlgorithm {
     SHA256,
+    SHA384,
     SHA512,
     SHA3_256,
     SHA3_512,
@@ -23,75 +23,79 @@ pub trait HashFunction: Send + Sync {
     fn block_size(&self) -> usize;
 }
 
+#[derive(Clone)]
 pub struct SHA256;
 
 impl SHA256 {
     pub fn new() -> Self {
         Self
     }
+}
+
+pub(super) const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+    0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// Software SHA-256 compression of one 64-byte block
+pub(super) fn sha256_compress_soft(h: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
     
-    fn process_block(&self, block: &[u8], h: &mut [u32; 8]) {
-        let mut w = [0u32; 64];
-        
-        for i in 0..16 {
-            w[i] = u32::from_be_bytes([
-                block[4 * i],
-                block[4 * i + 1],
-                block[4 * i + 2],
-                block[4 * i + 3],
-            ]);
-        }
-        
-        for i in 16..64 {
-            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
-            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
-            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
-        }
-        
-        let mut a = h[0];
-        let mut b = h[1];
-        let mut c = h[2];
-        let mut d = h[3];
-        let mut e = h[4];
-        let mut f = h[5];
-        let mut g = h[6];
-        let mut h_val = h[7];
-        
-        for i in 0..64 {
-            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
-            let ch = (e & f) ^ ((!e) & g);
-            let temp1 = h_val.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
-            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
-            let maj = (a & b) ^ (a & c) ^ (b & c);
-            let temp2 = s0.wrapping_add(maj);
-            
-            h_val = g;
-            g = f;
-            f = e;
-            e = d.wrapping_add(temp1);
-            d = c;
-            c = b;
-            b = a;
-            a = temp1.wrapping_add(temp2);
-        }
-        
-        h[0] = h[0].wrapping_add(a);
-        h[1] = h[1].wrapping_add(b);
-        h[2] = h[2].wrapping_add(c);
-        h[3] = h[3].wrapping_add(d);
-        h[4] = h[4].wrapping_add(e);
-        h[5] = h[5].wrapping_add(f);
-        h[6] = h[6].wrapping_add(g);
-        h[7] = h[7].wrapping_add(h_val);
+    for i in 0..16 {
+        w[i] = u32::from_be_bytes([
+            block[4 * i],
+            block[4 * i + 1],
+            block[4 * i + 2],
+            block[4 * i + 3],
+        ]);
     }
+    
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+    
+    let mut a = h[0];
+    let mut b = h[1];
+    let mut c = h[2];
+    let mut d = h[3];
+    let mut e = h[4];
+    let mut f = h[5];
+    let mut g = h[6];
+    let mut h_val = h[7];
+    
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ ((!e) & g);
+        let temp1 = h_val.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let temp2 = s0.wrapping_add(maj);
+        
+        h_val = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(temp1);
+        d = c;
+        c = b;
+        b = a;
+        a = temp1.wrapping_add(temp2);
+    }
+    
+    h[0] = h[0].wrapping_add(a);
+    h[1] = h[1].wrapping_add(b);
+    h[2] = h[2].wrapping_add(c);
+    h[3] = h[3].wrapping_add(d);
+    h[4] = h[4].wrapping_add(e);
+    h[5] = h[5].wrapping_add(f);
+    h[6] = h[6].wrapping_add(g);
+    h[7] = h[7].wrapping_add(h_val);
 }
 
 impl HashFunction for SHA256 {
     fn hash(&self, data: &[u8]) -> Vec<u8> {
-        let mut h = [
-            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
-            0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
-        ];
+        let mut h = SHA256_IV;
         
         let mut padded = data.to_vec();
         let bit_len = (data.len() as u64) * 8;
@@ -104,8 +108,13 @@ impl HashFunction for SHA256 {
         
         padded.extend_from_slice(&bit_len.to_be_bytes());
         
-        for chunk in padded.chunks(64) {
-            self.process_block(chunk, &mut h);
+        if hw_accel::use_sha_ni() {
+            #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+            unsafe { hw_accel::sha_ni::sha256_compress(&mut h, &padded) };
+        } else {
+            for chunk in padded.chunks(64) {
+                sha256_compress_soft(&mut h, chunk);
+            }
         }
         
         let mut result = Vec::with_capacity(32);
@@ -125,6 +134,7 @@ impl HashFunction for SHA256 {
     }
 }
 
+#[derive(Clone)]
 pub struct SHA512;
 
 impl SHA512 {
@@ -192,12 +202,10 @@ impl SHA512 {
     }
 }
 
-impl HashFunction for SHA512 {
-    fn hash(&self, data: &[u8]) -> Vec<u8> {
-        let mut h = [
-            0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
-            0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
-        ];
+impl SHA512 {
+    // Full SHA-512 state after hashing `data` from the given initial value
+    fn digest_state(&self, iv: [u64; 8], data: &[u8]) -> [u64; 8] {
+        let mut h = iv;
         
         let mut padded = data.to_vec();
         let bit_len = (data.len() as u128) * 8;
@@ -208,13 +216,20 @@ impl HashFunction for SHA512 {
             padded.push(0x00);
         }
         
-        padded.extend_from_slice(&0u64.to_be_bytes());
-        padded.extend_from_slice(&(bit_len as u64).to_be_bytes());
+        padded.extend_from_slice(&bit_len.to_be_bytes());
         
         for chunk in padded.chunks(128) {
             self.process_block(chunk, &mut h);
         }
         
+        h
+    }
+}
+
+impl HashFunction for SHA512 {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let h = self.digest_state(SHA512_IV, data);
+        
         let mut result = Vec::with_capacity(64);
         for val in h.iter() {
             result.extend_from_slice(&val.to_be_bytes());
@@ -232,111 +247,138 @@ impl HashFunction for SHA512 {
     }
 }
 
-pub struct SHA3_256 {
-    rate: usize,
-    capacity: usize,
-}
+// SHA-384: SHA-512 with its own initial value, truncated to 48 bytes
+#[derive(Clone)]
+pub struct SHA384;
 
-impl SHA3_256 {
+impl SHA384 {
     pub fn new() -> Self {
-        Self {
-            rate: 136,
-            capacity: 64,
+        Self
+    }
+}
+
+impl HashFunction for SHA384 {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let h = SHA512.digest_state(SHA384_IV, data);
+        
+        let mut result = Vec::with_capacity(48);
+        for val in h[..6].iter() {
+            result.extend_from_slice(&val.to_be_bytes());
         }
+        
+        result
     }
     
-    fn keccak_f(&self, state: &mut [u64; 25]) {
-        for round in 0..24 {
-            self.theta(state);
-            self.rho_pi(state);
-            self.chi(state);
-            self.iota(state, round);
-        }
+    fn digest_size(&self) -> usize {
+        48
     }
     
-    fn theta(&self, state: &mut [u64; 25]) {
-        let mut c = [0u64; 5];
-        let mut d = [0u64; 5];
-        
-        for x in 0..5 {
-            c[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+    fn block_size(&self) -> usize {
+        128
+    }
+}
+
+fn keccak_f(state: &mut [u64; 25]) {
+    for round in 0..24 {
+        theta(state);
+        rho_pi(state);
+        chi(state);
+        state[0] ^= KECCAK_RC[round];
+    }
+}
+
+fn theta(state: &mut [u64; 25]) {
+    let mut c = [0u64; 5];
+    let mut d = [0u64; 5];
+    
+    for x in 0..5 {
+        c[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+    }
+    
+    for x in 0..5 {
+        d[x] = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+    }
+    
+    for x in 0..5 {
+        for y in 0..5 {
+            state[y * 5 + x] ^= d[x];
         }
-        
+    }
+}
+
+fn rho_pi(state: &mut [u64; 25]) {
+    let mut temp = state[1];
+    let mut x = 1;
+    let mut y = 0;
+    
+    for t in 0..24 {
+        let next_x = y;
+        let next_y = (2 * x + 3 * y) % 5;
+        let index = next_y * 5 + next_x;
+        
+        let rotation = ((t + 1) * (t + 2) / 2) % 64;
+        let next_temp = state[index];
+        state[index] = temp.rotate_left(rotation as u32);
+        
+        temp = next_temp;
+        x = next_x;
+        y = next_y;
+    }
+}
+
+fn chi(state: &mut [u64; 25]) {
+    for y in 0..5 {
+        let mut row = [0u64; 5];
         for x in 0..5 {
-            d[x] = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+            row[x] = state[y * 5 + x];
         }
-        
         for x in 0..5 {
-            for y in 0..5 {
-                state[y * 5 + x] ^= d[x];
-            }
+            state[y * 5 + x] = row[x] ^ ((!row[(x + 1) % 5]) & row[(x + 2) % 5]);
         }
     }
+}
+
+// SHA-3 sponge: absorb at `rate` bytes per permutation, squeeze `output_size`
+fn sha3(rate: usize, output_size: usize, data: &[u8]) -> Vec<u8> {
+    let mut state = [0u64; 25];
+    let mut padded = data.to_vec();
     
-    fn rho_pi(&self, state: &mut [u64; 25]) {
-        let mut temp = state[1];
-        let mut x = 1;
-        let mut y = 0;
-        
-        for t in 0..24 {
-            let next_x = y;
-            let next_y = (2 * x + 3 * y) % 5;
-            let index = next_y * 5 + next_x;
-            
-            let rotation = ((t + 1) * (t + 2) / 2) % 64;
-            let next_temp = state[index];
-            state[index] = temp.rotate_left(rotation as u32);
-            
-            temp = next_temp;
-            x = next_x;
-            y = next_y;
-        }
+    padded.push(0x06);
+    
+    while (padded.len() % rate) != 0 {
+        padded.push(0x00);
     }
+    let last = padded.len() - 1;
+    padded[last] |= 0x80;
     
-    fn chi(&self, state: &mut [u64; 25]) {
-        for y in 0..5 {
-            let mut row = [0u64; 5];
-            for x in 0..5 {
-                row[x] = state[y * 5 + x];
-            }
-            for x in 0..5 {
-                state[y * 5 + x] = row[x] ^ ((!row[(x + 1) % 5]) & row[(x + 2) % 5]);
-            }
+    for chunk in padded.chunks(rate) {
+        for (i, word) in chunk.chunks_exact(8).enumerate() {
+            state[i] ^= u64::from_le_bytes(word.try_into().unwrap());
         }
+        keccak_f(&mut state);
     }
     
-    fn iota(&self, state: &mut [u64; 25], round: usize) {
-        state[0] ^= KECCAK_RC[round];
+    let mut output = Vec::with_capacity(output_size);
+    for lane in state.iter().take((output_size + 7) / 8) {
+        output.extend_from_slice(&lane.to_le_bytes());
+    }
+    output.truncate(output_size);
+    
+    output
+}
+
+#[derive(Clone)]
+pub struct SHA3_256;
+
+impl SHA3_256 {
+    pub fn new() -> Self {
+        Self
     }
 }
 
 impl HashFunction for SHA3_256 {
     fn hash(&self, data: &[u8]) -> Vec<u8> {
-        let mut state = [0u64; 25];
-        let mut padded = data.to_vec();
-        
-        padded.push(0x06);
-        
-        while (padded.len() % self.rate) != 0 {
-            padded.push(0x00);
-        }
-        padded[padded.len() - 1] |= 0x80;
-        
-        for chunk in padded.chunks(self.rate) {
-            for (i, byte_chunk) in chunk.chunks(8).enumerate() {
-                if byte_chunk.len() == 8 {
-                    state[i] ^= u64::from_le_bytes(byte_chunk.try_into().unwrap());
-                }
-            }
-            self.keccak_f(&mut state);
-        }
-        
-        let mut output = Vec::with_capacity(32);
-        for i in 0..4 {
-            output.extend_from_slice(&state[i].to_le_bytes());
-        }
-        
-        output
+        sha3(136, 32, data)
     }
     
     fn digest_size(&self) -> usize {
@@ -344,10 +386,34 @@ impl HashFunction for SHA3_256 {
     }
     
     fn block_size(&self) -> usize {
-        self.rate
+        136
+    }
+}
+
+#[derive(Clone)]
+pub struct SHA3_512;
+
+impl SHA3_512 {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl HashFunction for SHA3_512 {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        sha3(72, 64, data)
+    }
+    
+    fn digest_size(&self) -> usize {
+        64
+    }
+    
+    fn block_size(&self) -> usize {
+        72
     }
 }
 
+#[derive(Clone)]
 pub struct BLAKE2b {
     output_size: usize,
 }
@@ -452,18 +518,142 @@ impl HashFunction for BLAKE2b {
     }
 }
 
+// MD5 and SHA-1 are broken for collisions and are here only for the
+// protocols that still require them, such as RDP's standard security
+
+#[derive(Clone)]
+pub struct MD5;
+
+impl MD5 {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+// Message padded to whole blocks with its bit length last, as MD5 and
+// the SHA family do it
+fn md_pad(data: &[u8], little_endian: bool) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0x00);
+    }
+    if little_endian {
+        padded.extend_from_slice(&bit_len.to_le_bytes());
+    } else {
+        padded.extend_from_slice(&bit_len.to_be_bytes());
+    }
+    padded
+}
+
+impl HashFunction for MD5 {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let mut h: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+        
+        for block in md_pad(data, true).chunks(64) {
+            let m: Vec<u32> = block.chunks(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+            let [mut a, mut b, mut c, mut d] = h;
+            for i in 0..64 {
+                let (f, g) = match i / 16 {
+                    0 => ((b & c) | (!b & d), i),
+                    1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                    2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                    _ => (c ^ (b | !d), (7 * i) % 16),
+                };
+                let sum = a.wrapping_add(f).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+                a = d;
+                d = c;
+                c = b;
+                b = b.wrapping_add(sum.rotate_left(MD5_S[i / 16][i % 4]));
+            }
+            h[0] = h[0].wrapping_add(a);
+            h[1] = h[1].wrapping_add(b);
+            h[2] = h[2].wrapping_add(c);
+            h[3] = h[3].wrapping_add(d);
+        }
+        
+        h.iter().flat_map(|val| val.to_le_bytes()).collect()
+    }
+    
+    fn digest_size(&self) -> usize {
+        16
+    }
+    
+    fn block_size(&self) -> usize {
+        64
+    }
+}
+
+#[derive(Clone)]
+pub struct SHA1;
+
+impl SHA1 {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl HashFunction for SHA1 {
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+        
+        for block in md_pad(data, false).chunks(64) {
+            let mut w = [0u32; 80];
+            for i in 0..16 {
+                w[i] = u32::from_be_bytes([block[4 * i], block[4 * i + 1], block[4 * i + 2], block[4 * i + 3]]);
+            }
+            for i in 16..80 {
+                w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e] = h;
+            for (i, &word) in w.iter().enumerate() {
+                let (f, k) = match i / 20 {
+                    0 => ((b & c) | (!b & d), 0x5a827999),
+                    1 => (b ^ c ^ d, 0x6ed9eba1),
+                    2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                    _ => (b ^ c ^ d, 0xca62c1d6),
+                };
+                let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+                e = d;
+                d = c;
+                c = b.rotate_left(30);
+                b = a;
+                a = temp;
+            }
+            for (value, added) in h.iter_mut().zip([a, b, c, d, e]) {
+                *value = value.wrapping_add(added);
+            }
+        }
+        
+        h.iter().flat_map(|val| val.to_be_bytes()).collect()
+    }
+    
+    fn digest_size(&self) -> usize {
+        20
+    }
+    
+    fn block_size(&self) -> usize {
+        64
+    }
+}
+
 pub fn get_hash(algorithm: HashAlgorithm, _provider: CryptoProvider) -> CryptoResult<Box<dyn HashFunction>> {
     match algorithm {
         HashAlgorithm::SHA256 => Ok(Box::new(SHA256::new())),
+        HashAlgorithm::SHA384 => Ok(Box::new(SHA384::new())),
         HashAlgorithm::SHA512 => Ok(Box::new(SHA512::new())),
         HashAlgorithm::SHA3_256 => Ok(Box::new(SHA3_256::new())),
+        HashAlgorithm::SHA3_512 => Ok(Box::new(SHA3_512::new())),
         HashAlgorithm::BLAKE2b => Ok(Box::new(BLAKE2b::new(64))),
         HashAlgorithm::BLAKE2s => Ok(Box::new(BLAKE2b::new(32))),
+        HashAlgorithm::MD5 => Ok(Box::new(MD5::new())),
+        HashAlgorithm::SHA1 => Ok(Box::new(SHA1::new())),
         _ => Err(CryptoError::UnsupportedAlgorithm),
     }
 }
 
-const SHA256_K: [u32; 64] = [
+pub(super) const SHA256_K: [u32; 64] = [
     0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
     0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
     0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
@@ -474,6 +664,30 @@ const SHA256_K: [u32; 64] = [
     0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
 ];
 
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+// Rotations of each round's four steps
+const MD5_S: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+
+const SHA512_IV: [u64; 8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+const SHA384_IV: [u64; 8] = [
+    0xcbbb9d5dc1059ed8, 0x629a292a367cd507, 0x9159015a3070dd17, 0x152fecd8f70e5939,
+    0x67332667ffc00b31, 0x8eb44a8768581511, 0xdb0c2e0d64f98fa7, 0x47b5481dbefa4fa4,
+];
+
 const SHA512_K: [u64; 80] = [
     0x428a2f98d728ae22, 0x7137449123ef65cd, 0xb5c0fbcfec4d3b2f, 0xe9b5dba58189dbbc,
     0x3956c25bf348b538, 0x59f111f1b605d019, 0x923f82a4af194f9b, 0xab1c5ed5da6d8118,
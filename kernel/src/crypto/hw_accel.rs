@@ -1,11 +1,23 @@
-#![no_std]
+// CPU feature detection and the hardware-accelerated primitives
+//
+// Each accelerated routine has a software twin elsewhere in the crypto module;
+// callers ask `features()` which one to use. The hardware paths are checked
+// against the software ones at init and switched off if they disagree.
+//
+// The UEFI bootloader builds this module too, for signature checks. Its
+// target is soft-float and cannot enable SSE, so the SIMD paths are left
+// out there and the software ones always run.
 
 use alloc::vec::Vec;
 use core::arch::x86_64::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::serial_println;
+use lazy_static::lazy_static;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CryptoFeatures {
     pub aes_ni: bool,
+    pub pclmulqdq: bool,
     pub sha_ni: bool,
     pub rdrand: bool,
     pub rdseed: bool,
@@ -20,6 +32,7 @@ impl CryptoFeatures {
     pub fn detect() -> Self {
         let mut features = Self {
             aes_ni: false,
+            pclmulqdq: false,
             sha_ni: false,
             rdrand: false,
             rdseed: false,
@@ -29,16 +42,17 @@ impl CryptoFeatures {
             vaes: false,
             vpclmulqdq: false,
         };
-        
+
         #[cfg(target_arch = "x86_64")]
         unsafe {
             let cpuid_1 = __cpuid(1);
             let cpuid_7 = __cpuid_count(7, 0);
-            
+
+            features.pclmulqdq = (cpuid_1.ecx & (1 << 1)) != 0;
             features.aes_ni = (cpuid_1.ecx & (1 << 25)) != 0;
             features.rdrand = (cpuid_1.ecx & (1 << 30)) != 0;
             features.avx = (cpuid_1.ecx & (1 << 28)) != 0;
-            
+
             features.avx2 = (cpuid_7.ebx & (1 << 5)) != 0;
             features.rdseed = (cpuid_7.ebx & (1 << 18)) != 0;
             features.sha_ni = (cpuid_7.ebx & (1 << 29)) != 0;
@@ -46,342 +60,360 @@ impl CryptoFeatures {
             features.vaes = (cpuid_7.ecx & (1 << 9)) != 0;
             features.vpclmulqdq = (cpuid_7.ecx & (1 << 10)) != 0;
         }
-        
+
         features
     }
 }
 
+lazy_static! {
+    static ref FEATURES: CryptoFeatures = CryptoFeatures::detect();
+}
+
+// Cleared when a hardware path fails its self-test
+static AES_NI_USABLE: AtomicBool = AtomicBool::new(true);
+static CLMUL_USABLE: AtomicBool = AtomicBool::new(true);
+static SHA_NI_USABLE: AtomicBool = AtomicBool::new(true);
+
+// Whether the SIMD submodules below are compiled in at all
+const SIMD: bool = cfg!(all(target_arch = "x86_64", not(target_os = "uefi")));
+
+pub fn features() -> CryptoFeatures {
+    *FEATURES
+}
+
+pub fn use_aes_ni() -> bool {
+    SIMD && FEATURES.aes_ni && AES_NI_USABLE.load(Ordering::Relaxed)
+}
+
+pub fn use_clmul() -> bool {
+    SIMD && FEATURES.pclmulqdq && CLMUL_USABLE.load(Ordering::Relaxed)
+}
+
+pub fn use_sha_ni() -> bool {
+    SIMD && FEATURES.sha_ni && SHA_NI_USABLE.load(Ordering::Relaxed)
+}
+
 pub fn detect_hardware_crypto() -> bool {
-    let features = CryptoFeatures::detect();
+    let features = features();
     features.aes_ni || features.sha_ni || features.rdrand
 }
 
 pub fn init_hardware_crypto() {
-    let features = CryptoFeatures::detect();
-    
+    let features = features();
+
     if features.aes_ni {
-        log::info!("AES-NI hardware acceleration available");
+        serial_println!("[CRYPTO] AES-NI hardware acceleration available");
+    }
+    if features.pclmulqdq {
+        serial_println!("[CRYPTO] PCLMULQDQ carry-less multiply available");
     }
     if features.sha_ni {
-        log::info!("SHA-NI hardware acceleration available");
+        serial_println!("[CRYPTO] SHA-NI hardware acceleration available");
     }
     if features.rdrand {
-        log::info!("RDRAND hardware RNG available");
+        serial_println!("[CRYPTO] RDRAND hardware RNG available");
     }
     if features.rdseed {
-        log::info!("RDSEED hardware entropy available");
+        serial_println!("[CRYPTO] RDSEED hardware entropy available");
     }
     if features.avx2 {
-        log::info!("AVX2 SIMD acceleration available");
+        serial_println!("[CRYPTO] AVX2 SIMD acceleration available");
     }
     if features.avx512 {
-        log::info!("AVX-512 SIMD acceleration available");
+        serial_println!("[CRYPTO] AVX-512 SIMD acceleration available");
     }
+
+    self_test();
 }
 
-#[cfg(target_arch = "x86_64")]
-pub mod aes_ni {
-    use super::*;
-    use core::arch::x86_64::*;
-    
-    #[inline]
-    pub unsafe fn aes_encrypt_block(block: &[u8; 16], round_keys: &[__m128i]) -> [u8; 16] {
-        let mut state = _mm_loadu_si128(block.as_ptr() as *const __m128i);
-        
-        state = _mm_xor_si128(state, round_keys[0]);
-        
-        for i in 1..10 {
-            state = _mm_aesenc_si128(state, round_keys[i]);
+// Known-answer comparison of every hardware path against its software twin
+fn self_test() {
+    let key: [u8; 32] = core::array::from_fn(|i| i as u8);
+    let block: [u8; 16] = core::array::from_fn(|i| (i as u8).wrapping_mul(0x11));
+
+    if let (true, Ok(soft), Ok(hw)) = (
+        SIMD && FEATURES.aes_ni,
+        super::cipher::AesKey::new_software(&key),
+        super::cipher::AesKey::new(&key),
+    ) {
+        let (mut a, mut b) = (block, block);
+        soft.encrypt_block(&mut a);
+        hw.encrypt_block(&mut b);
+        let encrypt_ok = a == b;
+        hw.decrypt_block(&mut b);
+        if !encrypt_ok || b != block {
+            AES_NI_USABLE.store(false, Ordering::Relaxed);
+            serial_println!("[CRYPTO] AES-NI self-test failed, using software AES");
         }
-        
-        state = _mm_aesenclast_si128(state, round_keys[10]);
-        
-        let mut output = [0u8; 16];
-        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, state);
-        output
     }
-    
-    #[inline]
-    pub unsafe fn aes_decrypt_block(block: &[u8; 16], round_keys: &[__m128i]) -> [u8; 16] {
-        let mut state = _mm_loadu_si128(block.as_ptr() as *const __m128i);
-        
-        state = _mm_xor_si128(state, round_keys[10]);
-        
-        for i in (1..10).rev() {
-            state = _mm_aesdec_si128(state, round_keys[i]);
+
+    #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+    if FEATURES.pclmulqdq {
+        let h: [u8; 16] = core::array::from_fn(|i| (i as u8).wrapping_mul(0x3b) ^ 0xa5);
+        if unsafe { clmul::gf_mult(&block, &h) } != super::aead::gf_mult_soft(&block, &h) {
+            CLMUL_USABLE.store(false, Ordering::Relaxed);
+            serial_println!("[CRYPTO] PCLMULQDQ self-test failed, using software GHASH");
         }
-        
-        state = _mm_aesdeclast_si128(state, round_keys[0]);
-        
-        let mut output = [0u8; 16];
-        _mm_storeu_si128(output.as_mut_ptr() as *mut __m128i, state);
-        output
     }
-    
-    #[inline]
-    pub unsafe fn aes_key_expansion_128(key: &[u8; 16]) -> Vec<__m128i> {
-        let mut round_keys = Vec::with_capacity(11);
-        
-        let mut key_schedule = _mm_loadu_si128(key.as_ptr() as *const __m128i);
-        round_keys.push(key_schedule);
-        
-        for rcon in [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36] {
-            key_schedule = aes_128_key_expansion_assist(key_schedule, rcon);
-            round_keys.push(key_schedule);
+
+    #[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+    if FEATURES.sha_ni {
+        let data = [0x61u8; 200];
+        let mut soft = super::hash::SHA256_IV;
+        let mut hw = super::hash::SHA256_IV;
+        for chunk in data[..192].chunks(64) {
+            super::hash::sha256_compress_soft(&mut soft, chunk);
+        }
+        unsafe { sha_ni::sha256_compress(&mut hw, &data[..192]) };
+        if soft != hw {
+            SHA_NI_USABLE.store(false, Ordering::Relaxed);
+            serial_println!("[CRYPTO] SHA-NI self-test failed, using software SHA-256");
         }
-        
-        round_keys
     }
-    
-    #[inline]
-    unsafe fn aes_128_key_expansion_assist(key: __m128i, rcon: u8) -> __m128i {
-        let mut keygened = _mm_aeskeygenassist_si128(key, rcon);
-        keygened = _mm_shuffle_epi32(keygened, 0xff);
-        
+}
+
+#[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+pub mod aes_ni {
+    use super::*;
+
+    // Expanded encryption and decryption schedules
+    #[derive(Clone)]
+    pub struct AesNiKey {
+        enc: Vec<__m128i>,
+        dec: Vec<__m128i>,
+    }
+
+    impl AesNiKey {
+        /// Expand a 128- or 256-bit key; AES-192 stays on the software path
+        #[target_feature(enable = "aes,sse2")]
+        pub unsafe fn new(key: &[u8]) -> Option<Self> {
+            let enc = match key.len() {
+                16 => key_expansion_128(key),
+                32 => key_expansion_256(key),
+                _ => return None,
+            };
+
+            let rounds = enc.len() - 1;
+            let mut dec = Vec::with_capacity(enc.len());
+            dec.push(enc[rounds]);
+            for i in (1..rounds).rev() {
+                dec.push(_mm_aesimc_si128(enc[i]));
+            }
+            dec.push(enc[0]);
+
+            Some(Self { enc, dec })
+        }
+
+        #[target_feature(enable = "aes,sse2")]
+        pub unsafe fn encrypt_block(&self, block: &mut [u8; 16]) {
+            let rounds = self.enc.len() - 1;
+            let mut state = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+
+            state = _mm_xor_si128(state, self.enc[0]);
+            for i in 1..rounds {
+                state = _mm_aesenc_si128(state, self.enc[i]);
+            }
+            state = _mm_aesenclast_si128(state, self.enc[rounds]);
+
+            _mm_storeu_si128(block.as_mut_ptr() as *mut __m128i, state);
+        }
+
+        #[target_feature(enable = "aes,sse2")]
+        pub unsafe fn decrypt_block(&self, block: &mut [u8; 16]) {
+            let rounds = self.dec.len() - 1;
+            let mut state = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+
+            state = _mm_xor_si128(state, self.dec[0]);
+            for i in 1..rounds {
+                state = _mm_aesdec_si128(state, self.dec[i]);
+            }
+            state = _mm_aesdeclast_si128(state, self.dec[rounds]);
+
+            _mm_storeu_si128(block.as_mut_ptr() as *mut __m128i, state);
+        }
+    }
+
+    // Fold the previous round key into itself for the next schedule word
+    #[target_feature(enable = "sse2")]
+    unsafe fn fold(key: __m128i, keygened: __m128i) -> __m128i {
         let mut key = key;
         key = _mm_xor_si128(key, _mm_slli_si128(key, 4));
         key = _mm_xor_si128(key, _mm_slli_si128(key, 4));
         key = _mm_xor_si128(key, _mm_slli_si128(key, 4));
-        
         _mm_xor_si128(key, keygened)
     }
-    
-    #[inline]
-    pub unsafe fn aes_key_expansion_256(key: &[u8; 32]) -> Vec<__m128i> {
+
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn expand_128<const RCON: i32>(key: __m128i) -> __m128i {
+        let keygened = _mm_shuffle_epi32(_mm_aeskeygenassist_si128::<RCON>(key), 0xff);
+        fold(key, keygened)
+    }
+
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn key_expansion_128(key: &[u8]) -> Vec<__m128i> {
+        let mut round_keys = Vec::with_capacity(11);
+        let mut k = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+        round_keys.push(k);
+
+        macro_rules! round {
+            ($($rcon:literal),*) => {
+                $(
+                    k = expand_128::<$rcon>(k);
+                    round_keys.push(k);
+                )*
+            };
+        }
+        round!(0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36);
+
+        round_keys
+    }
+
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn expand_256_even<const RCON: i32>(key1: __m128i, key2: __m128i) -> __m128i {
+        let keygened = _mm_shuffle_epi32(_mm_aeskeygenassist_si128::<RCON>(key2), 0xff);
+        fold(key1, keygened)
+    }
+
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn expand_256_odd(key1: __m128i, key2: __m128i) -> __m128i {
+        let keygened = _mm_shuffle_epi32(_mm_aeskeygenassist_si128::<0>(key1), 0xaa);
+        fold(key2, keygened)
+    }
+
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn key_expansion_256(key: &[u8]) -> Vec<__m128i> {
         let mut round_keys = Vec::with_capacity(15);
-        
         let mut key1 = _mm_loadu_si128(key.as_ptr() as *const __m128i);
         let mut key2 = _mm_loadu_si128(key[16..].as_ptr() as *const __m128i);
-        
         round_keys.push(key1);
         round_keys.push(key2);
-        
-        for i in 0..6 {
-            key1 = aes_256_key_expansion_assist_1(key1, key2, 1 << i);
-            round_keys.push(key1);
-            
-            key2 = aes_256_key_expansion_assist_2(key1, key2);
-            round_keys.push(key2);
+
+        macro_rules! round {
+            ($($rcon:literal),*) => {
+                $(
+                    key1 = expand_256_even::<$rcon>(key1, key2);
+                    round_keys.push(key1);
+                    key2 = expand_256_odd(key1, key2);
+                    round_keys.push(key2);
+                )*
+            };
         }
-        
-        key1 = aes_256_key_expansion_assist_1(key1, key2, 0x40);
+        round!(0x01, 0x02, 0x04, 0x08, 0x10, 0x20);
+
+        key1 = expand_256_even::<0x40>(key1, key2);
         round_keys.push(key1);
-        
+
         round_keys
     }
-    
-    #[inline]
-    unsafe fn aes_256_key_expansion_assist_1(key1: __m128i, key2: __m128i, rcon: u8) -> __m128i {
-        let mut keygened = _mm_aeskeygenassist_si128(key2, rcon);
-        keygened = _mm_shuffle_epi32(keygened, 0xff);
-        
-        let mut key1 = key1;
-        key1 = _mm_xor_si128(key1, _mm_slli_si128(key1, 4));
-        key1 = _mm_xor_si128(key1, _mm_slli_si128(key1, 4));
-        key1 = _mm_xor_si128(key1, _mm_slli_si128(key1, 4));
-        
-        _mm_xor_si128(key1, keygened)
-    }
-    
-    #[inline]
-    unsafe fn aes_256_key_expansion_assist_2(key1: __m128i, key2: __m128i) -> __m128i {
-        let mut keygened = _mm_aeskeygenassist_si128(key1, 0);
-        keygened = _mm_shuffle_epi32(keygened, 0xaa);
-        
-        let mut key2 = key2;
-        key2 = _mm_xor_si128(key2, _mm_slli_si128(key2, 4));
-        key2 = _mm_xor_si128(key2, _mm_slli_si128(key2, 4));
-        key2 = _mm_xor_si128(key2, _mm_slli_si128(key2, 4));
-        
-        _mm_xor_si128(key2, keygened)
-    }
-    
-    pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8], nonce: &[u8; 12], aad: &[u8]) -> Vec<u8> {
-        unsafe {
-            let round_keys = if key.len() == 16 {
-                aes_key_expansion_128(key.try_into().unwrap())
-            } else {
-                aes_key_expansion_256(key.try_into().unwrap())
-            };
-            
-            let mut ciphertext = Vec::with_capacity(plaintext.len() + 16);
-            
-            let mut counter = [0u8; 16];
-            counter[..12].copy_from_slice(nonce);
-            counter[15] = 1;
-            
-            for chunk in plaintext.chunks(16) {
-                counter[15] = counter[15].wrapping_add(1);
-                let keystream = aes_encrypt_block(&counter, &round_keys);
-                
-                for (i, &byte) in chunk.iter().enumerate() {
-                    ciphertext.push(byte ^ keystream[i]);
-                }
-            }
-            
-            let h = aes_encrypt_block(&[0u8; 16], &round_keys);
-            let mut tag = ghash(&h, aad, &ciphertext);
-            
-            counter[15] = 1;
-            let j0_encrypted = aes_encrypt_block(&counter, &round_keys);
-            for i in 0..16 {
-                tag[i] ^= j0_encrypted[i];
-            }
-            
-            ciphertext.extend_from_slice(&tag);
-            ciphertext
-        }
-    }
-    
-    fn ghash(h: &[u8; 16], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
-        let mut y = [0u8; 16];
-        
-        for chunk in aad.chunks(16) {
-            for i in 0..chunk.len() {
-                y[i] ^= chunk[i];
-            }
-            gf_mult_ni(&mut y, h);
-        }
-        
-        for chunk in ciphertext.chunks(16) {
-            for i in 0..chunk.len() {
-                y[i] ^= chunk[i];
-            }
-            gf_mult_ni(&mut y, h);
-        }
-        
-        let aad_bits = (aad.len() * 8) as u64;
-        let ct_bits = (ciphertext.len() * 8) as u64;
-        
-        for (i, &byte) in aad_bits.to_be_bytes().iter().enumerate() {
-            y[i] ^= byte;
-        }
-        for (i, &byte) in ct_bits.to_be_bytes().iter().enumerate() {
-            y[8 + i] ^= byte;
-        }
-        
-        gf_mult_ni(&mut y, h);
-        y
-    }
-    
-    #[inline]
-    fn gf_mult_ni(x: &mut [u8; 16], y: &[u8; 16]) {
-        unsafe {
-            let a = _mm_loadu_si128(x.as_ptr() as *const __m128i);
-            let b = _mm_loadu_si128(y.as_ptr() as *const __m128i);
-            
-            let tmp1 = _mm_clmulepi64_si128(a, b, 0x00);
-            let tmp2 = _mm_clmulepi64_si128(a, b, 0x01);
-            let tmp3 = _mm_clmulepi64_si128(a, b, 0x10);
-            let tmp4 = _mm_clmulepi64_si128(a, b, 0x11);
-            
-            let tmp5 = _mm_xor_si128(tmp2, tmp3);
-            let tmp6 = _mm_slli_si128(tmp5, 8);
-            let tmp7 = _mm_srli_si128(tmp5, 8);
-            
-            let tmp8 = _mm_xor_si128(tmp1, tmp6);
-            let tmp9 = _mm_xor_si128(tmp4, tmp7);
-            
-            let poly = _mm_set_epi64x(0, 0xc200000000000000u64 as i64);
-            
-            let tmp10 = _mm_clmulepi64_si128(tmp8, poly, 0x10);
-            let tmp11 = _mm_shuffle_epi32(tmp8, 0x4e);
-            let tmp12 = _mm_xor_si128(tmp10, tmp11);
-            
-            let tmp13 = _mm_clmulepi64_si128(tmp12, poly, 0x10);
-            let tmp14 = _mm_shuffle_epi32(tmp12, 0x4e);
-            let tmp15 = _mm_xor_si128(tmp13, tmp14);
-            
-            let result = _mm_xor_si128(tmp15, tmp9);
-            _mm_storeu_si128(x.as_mut_ptr() as *mut __m128i, result);
-        }
-    }
 }
 
-#[cfg(target_arch = "x86_64")]
-pub mod sha_ni {
+#[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+pub mod clmul {
     use super::*;
-    use core::arch::x86_64::*;
-    
-    #[inline]
-    pub unsafe fn sha256_hw(data: &[u8]) -> [u8; 32] {
-        let mut h0 = _mm_set_epi32(
-            0x6a09e667i32, 0xbb67ae85i32, 0x3c6ef372i32, 0xa54ff53ai32,
+
+    /// GHASH multiply in GF(2^128) on big-endian GCM blocks
+    #[target_feature(enable = "pclmulqdq,sse2,ssse3")]
+    pub unsafe fn gf_mult(x: &[u8; 16], y: &[u8; 16]) -> [u8; 16] {
+        let reverse = _mm_set_epi8(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        let a = _mm_shuffle_epi8(_mm_loadu_si128(x.as_ptr() as *const __m128i), reverse);
+        let b = _mm_shuffle_epi8(_mm_loadu_si128(y.as_ptr() as *const __m128i), reverse);
+
+        // 256-bit carry-less product
+        let mut lo = _mm_clmulepi64_si128(a, b, 0x00);
+        let mut mid = _mm_xor_si128(
+            _mm_clmulepi64_si128(a, b, 0x10),
+            _mm_clmulepi64_si128(a, b, 0x01),
+        );
+        let mut hi = _mm_clmulepi64_si128(a, b, 0x11);
+        lo = _mm_xor_si128(lo, _mm_slli_si128(mid, 8));
+        hi = _mm_xor_si128(hi, _mm_srli_si128(mid, 8));
+
+        // GCM's bit order is reflected, so shift the product left by one
+        let lo_carry = _mm_srli_epi32(lo, 31);
+        let hi_carry = _mm_srli_epi32(hi, 31);
+        lo = _mm_slli_epi32(lo, 1);
+        hi = _mm_slli_epi32(hi, 1);
+        let cross = _mm_srli_si128(lo_carry, 12);
+        hi = _mm_or_si128(hi, _mm_slli_si128(hi_carry, 4));
+        hi = _mm_or_si128(hi, cross);
+        lo = _mm_or_si128(lo, _mm_slli_si128(lo_carry, 4));
+
+        // Reduce modulo x^128 + x^7 + x^2 + x + 1
+        mid = _mm_xor_si128(
+            _mm_xor_si128(_mm_slli_epi32(lo, 31), _mm_slli_epi32(lo, 30)),
+            _mm_slli_epi32(lo, 25),
         );
-        let mut h1 = _mm_set_epi32(
-            0x510e527fi32, 0x9b05688ci32, 0x1f83d9abi32, 0x5be0cd19i32,
+        let spill = _mm_srli_si128(mid, 4);
+        lo = _mm_xor_si128(lo, _mm_slli_si128(mid, 12));
+        let mut folded = _mm_xor_si128(
+            _mm_xor_si128(_mm_srli_epi32(lo, 1), _mm_srli_epi32(lo, 2)),
+            _mm_srli_epi32(lo, 7),
         );
-        
-        let mut padded = data.to_vec();
-        let bit_len = (data.len() as u64) * 8;
-        
-        padded.push(0x80);
-        while (padded.len() % 64) != 56 {
-            padded.push(0x00);
-        }
-        padded.extend_from_slice(&bit_len.to_be_bytes());
-        
-        for chunk in padded.chunks(64) {
-            let msg = chunk.as_ptr() as *const __m128i;
-            
-            let abef_save = h0;
-            let cdgh_save = h1;
-            
-            let mut msg0 = _mm_loadu_si128(msg.add(0));
-            let mut msg1 = _mm_loadu_si128(msg.add(1));
-            let mut msg2 = _mm_loadu_si128(msg.add(2));
-            let mut msg3 = _mm_loadu_si128(msg.add(3));
-            
-            msg0 = _mm_shuffle_epi8(msg0, _mm_set_epi8(
-                12, 13, 14, 15, 8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3
-            ));
-            msg1 = _mm_shuffle_epi8(msg1, _mm_set_epi8(
-                12, 13, 14, 15, 8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3
-            ));
-            msg2 = _mm_shuffle_epi8(msg2, _mm_set_epi8(
-                12, 13, 14, 15, 8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3
-            ));
-            msg3 = _mm_shuffle_epi8(msg3, _mm_set_epi8(
-                12, 13, 14, 15, 8, 9, 10, 11, 4, 5, 6, 7, 0, 1, 2, 3
-            ));
-            
-            let k = SHA256_K.as_ptr() as *const __m128i;
-            
-            h1 = _mm_sha256rnds2_epu32(h1, h0, _mm_add_epi32(msg0, _mm_loadu_si128(k.add(0))));
-            h0 = _mm_sha256rnds2_epu32(h0, h1, _mm_shuffle_epi32(
-                _mm_add_epi32(msg0, _mm_loadu_si128(k.add(0))), 0x0e
-            ));
-            
-            msg0 = _mm_sha256msg1_epu32(msg0, msg1);
-            msg0 = _mm_sha256msg2_epu32(_mm_add_epi32(msg0, _mm_alignr_epi8(msg3, msg2, 4)), msg3);
-            
-            h0 = _mm_add_epi32(h0, abef_save);
-            h1 = _mm_add_epi32(h1, cdgh_save);
-        }
-        
-        let mut result = [0u8; 32];
-        _mm_storeu_si128(result.as_mut_ptr() as *mut __m128i, h0);
-        _mm_storeu_si128(result[16..].as_mut_ptr() as *mut __m128i, h1);
-        
+        folded = _mm_xor_si128(folded, spill);
+        lo = _mm_xor_si128(lo, folded);
+        hi = _mm_xor_si128(hi, lo);
+
+        let mut result = [0u8; 16];
+        _mm_storeu_si128(result.as_mut_ptr() as *mut __m128i, _mm_shuffle_epi8(hi, reverse));
         result
     }
-    
-    const SHA256_K: [u32; 64] = [
-        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5,
-        0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
-        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
-        0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
-        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
-        0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
-        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
-        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
-        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
-        0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
-        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3,
-        0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
-        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5,
-        0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
-        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
-        0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
-    ];
-}
\ No newline at end of file
+}
+
+#[cfg(all(target_arch = "x86_64", not(target_os = "uefi")))]
+pub mod sha_ni {
+    use super::*;
+
+    /// SHA-256 compression over whole 64-byte blocks
+    #[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+    pub unsafe fn sha256_compress(state: &mut [u32; 8], blocks: &[u8]) {
+        let byte_swap = _mm_set_epi64x(0x0c0d0e0f08090a0bu64 as i64, 0x0405060700010203u64 as i64);
+
+        // Rearrange the state into the ABEF/CDGH form the instructions use
+        let tmp = _mm_shuffle_epi32(_mm_loadu_si128(state.as_ptr() as *const __m128i), 0xb1);
+        let efgh = _mm_shuffle_epi32(_mm_loadu_si128(state.as_ptr().add(4) as *const __m128i), 0x1b);
+        let mut abef = _mm_alignr_epi8(tmp, efgh, 8);
+        let mut cdgh = _mm_blend_epi16(efgh, tmp, 0xf0);
+
+        for block in blocks.chunks_exact(64) {
+            let abef_save = abef;
+            let cdgh_save = cdgh;
+            let mut msgs = [_mm_setzero_si128(); 4];
+
+            for i in 0..16 {
+                if i < 4 {
+                    let words = _mm_loadu_si128(block.as_ptr().add(16 * i) as *const __m128i);
+                    msgs[i] = _mm_shuffle_epi8(words, byte_swap);
+                }
+                let current = msgs[i % 4];
+
+                let k = _mm_loadu_si128(super::super::hash::SHA256_K.as_ptr().add(4 * i) as *const __m128i);
+                let msg = _mm_add_epi32(current, k);
+                cdgh = _mm_sha256rnds2_epu32(cdgh, abef, msg);
+
+                // Message schedule for the rounds four groups ahead
+                if (3..15).contains(&i) {
+                    let next = (i + 1) % 4;
+                    let tmp = _mm_alignr_epi8(current, msgs[(i + 3) % 4], 4);
+                    msgs[next] = _mm_sha256msg2_epu32(_mm_add_epi32(msgs[next], tmp), current);
+                }
+
+                abef = _mm_sha256rnds2_epu32(abef, cdgh, _mm_shuffle_epi32(msg, 0x0e));
+
+                if (1..13).contains(&i) {
+                    let prev = (i + 3) % 4;
+                    msgs[prev] = _mm_sha256msg1_epu32(msgs[prev], current);
+                }
+            }
+
+            abef = _mm_add_epi32(abef, abef_save);
+            cdgh = _mm_add_epi32(cdgh, cdgh_save);
+        }
+
+        let feba = _mm_shuffle_epi32(abef, 0x1b);
+        let dchg = _mm_shuffle_epi32(cdgh, 0xb1);
+        let abcd = _mm_blend_epi16(feba, dchg, 0xf0);
+        let efgh = _mm_alignr_epi8(dchg, feba, 8);
+        _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, abcd);
+        _mm_storeu_si128(state.as_mut_ptr().add(4) as *mut __m128i, efgh);
+    }
+}
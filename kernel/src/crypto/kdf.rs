@@ -1,13 +1,14 @@
-#![no_std]
-
+use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
 use super::errors::{CryptoError, CryptoResult};
-use super::hash::{HashFunction, SHA256, SHA512};
+use super::hash::{HashFunction, SHA1, SHA256, SHA512};
 use super::mac::{Hmac, Mac};
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KdfAlgorithm {
+    PBKDF2SHA1,
     PBKDF2SHA256,
     PBKDF2SHA512,
     Argon2id,
@@ -29,7 +30,7 @@ impl<H: HashFunction> PBKDF2<H> {
     }
 }
 
-impl<H: HashFunction> KeyDerivation for PBKDF2<H> {
+impl<H: HashFunction + Clone> KeyDerivation for PBKDF2<H> {
     fn derive(&self, password: &[u8], salt: &[u8], iterations: u32, key_len: usize) -> CryptoResult<Vec<u8>> {
         if iterations == 0 {
             return Err(CryptoError::InvalidParameter);
@@ -205,15 +206,15 @@ impl KeyDerivation for Argon2id {
                                 v[j] = memory[prev_idx][i + j] ^ memory[ref_block][i + j];
                             }
                             
-                            let (v[0], v[4], v[8], v[12]) = self.g(v[0], v[4], v[8], v[12]);
-                            let (v[1], v[5], v[9], v[13]) = self.g(v[1], v[5], v[9], v[13]);
-                            let (v[2], v[6], v[10], v[14]) = self.g(v[2], v[6], v[10], v[14]);
-                            let (v[3], v[7], v[11], v[15]) = self.g(v[3], v[7], v[11], v[15]);
+                            (v[0], v[4], v[8], v[12]) = self.g(v[0], v[4], v[8], v[12]);
+                            (v[1], v[5], v[9], v[13]) = self.g(v[1], v[5], v[9], v[13]);
+                            (v[2], v[6], v[10], v[14]) = self.g(v[2], v[6], v[10], v[14]);
+                            (v[3], v[7], v[11], v[15]) = self.g(v[3], v[7], v[11], v[15]);
                             
-                            let (v[0], v[5], v[10], v[15]) = self.g(v[0], v[5], v[10], v[15]);
-                            let (v[1], v[6], v[11], v[12]) = self.g(v[1], v[6], v[11], v[12]);
-                            let (v[2], v[7], v[8], v[13]) = self.g(v[2], v[7], v[8], v[13]);
-                            let (v[3], v[4], v[9], v[14]) = self.g(v[3], v[4], v[9], v[14]);
+                            (v[0], v[5], v[10], v[15]) = self.g(v[0], v[5], v[10], v[15]);
+                            (v[1], v[6], v[11], v[12]) = self.g(v[1], v[6], v[11], v[12]);
+                            (v[2], v[7], v[8], v[13]) = self.g(v[2], v[7], v[8], v[13]);
+                            (v[3], v[4], v[9], v[14]) = self.g(v[3], v[4], v[9], v[14]);
                             
                             for j in 0..16 {
                                 memory[idx][i + j] = memory[idx][i + j] ^ v[j];
@@ -245,17 +246,20 @@ pub struct HKDF<H: HashFunction> {
     hasher: H,
 }
 
-impl<H: HashFunction> HKDF<H> {
+impl<H: HashFunction + Clone> HKDF<H> {
     pub fn new(hasher: H) -> Self {
         Self { hasher }
     }
     
-    fn extract(&self, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
+    /// HKDF-Extract: a pseudorandom key from input keying material
+    pub fn extract(&self, salt: &[u8], ikm: &[u8]) -> Vec<u8> {
         let hmac = Hmac::new(self.hasher.clone());
         hmac.compute(salt, ikm).unwrap()
     }
     
-    fn expand(&self, prk: &[u8], info: &[u8], output_len: usize) -> CryptoResult<Vec<u8>> {
+    /// HKDF-Expand: `output_len` bytes of keying material from a
+    /// pseudorandom key
+    pub fn expand(&self, prk: &[u8], info: &[u8], output_len: usize) -> CryptoResult<Vec<u8>> {
         let hmac = Hmac::new(self.hasher.clone());
         let hash_len = hmac.tag_size();
         
@@ -281,7 +285,7 @@ impl<H: HashFunction> HKDF<H> {
     }
 }
 
-impl<H: HashFunction> KeyDerivation for HKDF<H> {
+impl<H: HashFunction + Clone> KeyDerivation for HKDF<H> {
     fn derive(&self, password: &[u8], salt: &[u8], _iterations: u32, key_len: usize) -> CryptoResult<Vec<u8>> {
         let prk = self.extract(salt, password);
         self.expand(&prk, b"", key_len)
@@ -290,6 +294,7 @@ impl<H: HashFunction> KeyDerivation for HKDF<H> {
 
 pub fn get_kdf(algorithm: KdfAlgorithm, _provider: CryptoProvider) -> CryptoResult<Box<dyn KeyDerivation>> {
     match algorithm {
+        KdfAlgorithm::PBKDF2SHA1 => Ok(Box::new(PBKDF2::new(SHA1::new()))),
         KdfAlgorithm::PBKDF2SHA256 => Ok(Box::new(PBKDF2::new(SHA256::new()))),
         KdfAlgorithm::PBKDF2SHA512 => Ok(Box::new(PBKDF2::new(SHA512::new()))),
         KdfAlgorithm::Argon2id => Ok(Box::new(Argon2id::new(4096, 3, 1))),
@@ -1,13 +1,14 @@
-#![no_std]
-
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use super::errors::{CryptoError, CryptoResult};
-use super::hash::{HashFunction, SHA256, SHA512};
+use super::hash::{HashFunction, SHA1, SHA256, SHA384, SHA512};
 use super::CryptoProvider;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MacAlgorithm {
+    HmacSHA1,
     HmacSHA256,
+    HmacSHA384,
     HmacSHA512,
     Poly1305,
     CMAC,
@@ -32,7 +33,7 @@ impl<H: HashFunction> Hmac<H> {
     fn compute_hmac(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
         let block_size = self.hasher.block_size();
         
-        let mut key_block = if key.len() > block_size {
+        let key_block = if key.len() > block_size {
             let mut hashed = self.hasher.hash(key);
             hashed.resize(block_size, 0);
             hashed
@@ -90,107 +91,108 @@ impl Poly1305 {
         Self
     }
     
-    fn clamp(r: &mut [u8; 16]) {
-        r[3] &= 0x0f;
-        r[7] &= 0x0f;
-        r[11] &= 0x0f;
-        r[15] &= 0x0f;
-        
-        r[4] &= 0xfc;
-        r[8] &= 0xfc;
-        r[12] &= 0xfc;
-    }
-    
+    // poly1305-donna with 26-bit limbs
     fn compute_poly1305(&self, key: &[u8], data: &[u8]) -> CryptoResult<Vec<u8>> {
         if key.len() != 32 {
             return Err(CryptoError::InvalidKeySize);
         }
         
-        let mut r = [0u8; 16];
-        let mut s = [0u8; 16];
-        r.copy_from_slice(&key[0..16]);
-        s.copy_from_slice(&key[16..32]);
+        let le32 = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        const MASK: u32 = 0x3ffffff;
         
-        Self::clamp(&mut r);
+        // Clamped r
+        let r0 = le32(key, 0) & 0x3ffffff;
+        let r1 = (le32(key, 3) >> 2) & 0x3ffff03;
+        let r2 = (le32(key, 6) >> 4) & 0x3ffc0ff;
+        let r3 = (le32(key, 9) >> 6) & 0x3f03fff;
+        let r4 = (le32(key, 12) >> 8) & 0x00fffff;
+        let (r0, r1, r2, r3, r4) = (r0 as u64, r1 as u64, r2 as u64, r3 as u64, r4 as u64);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
         
-        let mut h0 = 0u64;
-        let mut h1 = 0u64;
-        let mut h2 = 0u64;
-        
-        let r0 = u64::from(u32::from_le_bytes([r[0], r[1], r[2], r[3]]));
-        let r1 = u64::from(u32::from_le_bytes([r[4], r[5], r[6], r[7]]));
-        let r2 = u64::from(u32::from_le_bytes([r[8], r[9], r[10], r[11]]));
-        let r3 = u64::from(u32::from_le_bytes([r[12], r[13], r[14], r[15]]));
-        
-        let s0 = u64::from(u32::from_le_bytes([s[0], s[1], s[2], s[3]]));
-        let s1 = u64::from(u32::from_le_bytes([s[4], s[5], s[6], s[7]]));
-        let s2 = u64::from(u32::from_le_bytes([s[8], s[9], s[10], s[11]]));
-        let s3 = u64::from(u32::from_le_bytes([s[12], s[13], s[14], s[15]]));
+        let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
         
         for chunk in data.chunks(16) {
             let mut block = [0u8; 17];
             block[..chunk.len()].copy_from_slice(chunk);
             block[chunk.len()] = 1;
             
-            let t0 = u64::from(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
-            let t1 = u64::from(u32::from_le_bytes([block[4], block[5], block[6], block[7]]));
-            let t2 = u64::from(u32::from_le_bytes([block[8], block[9], block[10], block[11]]));
-            let t3 = u64::from(u32::from_le_bytes([block[12], block[13], block[14], block[15]]));
-            let t4 = u64::from(block[16]);
-            
-            h0 = h0.wrapping_add(t0);
-            h1 = h1.wrapping_add(t1);
-            h2 = h2.wrapping_add(t2.wrapping_add(t3 << 32).wrapping_add(t4 << 40));
+            h0 += le32(&block, 0) & MASK;
+            h1 += (le32(&block, 3) >> 2) & MASK;
+            h2 += (le32(&block, 6) >> 4) & MASK;
+            h3 += (le32(&block, 9) >> 6) & MASK;
+            h4 += (le32(&block, 12) >> 8) | ((block[16] as u32) << 24);
             
-            let d0 = h0.wrapping_mul(r0)
-                .wrapping_add(h1.wrapping_mul(5 * r3))
-                .wrapping_add(h2.wrapping_mul(5 * r2));
-            let d1 = h0.wrapping_mul(r1)
-                .wrapping_add(h1.wrapping_mul(r0))
-                .wrapping_add(h2.wrapping_mul(5 * r3));
-            let d2 = h0.wrapping_mul(r2)
-                .wrapping_add(h1.wrapping_mul(r1))
-                .wrapping_add(h2.wrapping_mul(r0));
+            let (a0, a1, a2, a3, a4) = (h0 as u64, h1 as u64, h2 as u64, h3 as u64, h4 as u64);
+            let d0 = a0 * r0 + a1 * s4 + a2 * s3 + a3 * s2 + a4 * s1;
+            let mut d1 = a0 * r1 + a1 * r0 + a2 * s4 + a3 * s3 + a4 * s2;
+            let mut d2 = a0 * r2 + a1 * r1 + a2 * r0 + a3 * s4 + a4 * s3;
+            let mut d3 = a0 * r3 + a1 * r2 + a2 * r1 + a3 * r0 + a4 * s4;
+            let mut d4 = a0 * r4 + a1 * r3 + a2 * r2 + a3 * r1 + a4 * r0;
             
-            h0 = d0 & 0xfffffffffff;
-            h1 = (d0 >> 44) | ((d1 & 0xfffffffffff) << 20);
-            h2 = ((d1 >> 24) | (d2 << 40)) & 0x3ffffffffff;
-            
-            let carry = h2 >> 42;
-            h2 &= 0x3ffffffffff;
-            h0 = h0.wrapping_add(carry.wrapping_mul(5));
-            h1 = h1.wrapping_add(h0 >> 44);
-            h0 &= 0xfffffffffff;
+            d1 += d0 >> 26;
+            h0 = d0 as u32 & MASK;
+            d2 += d1 >> 26;
+            h1 = d1 as u32 & MASK;
+            d3 += d2 >> 26;
+            h2 = d2 as u32 & MASK;
+            d4 += d3 >> 26;
+            h3 = d3 as u32 & MASK;
+            h0 += (d4 >> 26) as u32 * 5;
+            h4 = d4 as u32 & MASK;
+            h1 += h0 >> 26;
+            h0 &= MASK;
         }
         
-        h2 = h2.wrapping_add(h1 >> 44);
-        h1 &= 0xfffffffffff;
-        
+        // Fully carry h
+        h2 += h1 >> 26;
+        h1 &= MASK;
+        h3 += h2 >> 26;
+        h2 &= MASK;
+        h4 += h3 >> 26;
+        h3 &= MASK;
+        h0 += (h4 >> 26) * 5;
+        h4 &= MASK;
+        h1 += h0 >> 26;
+        h0 &= MASK;
+        
+        // Compute h - p and keep it if it did not go negative
         let mut g0 = h0.wrapping_add(5);
-        let mut g1 = h1.wrapping_add(g0 >> 44);
-        g0 &= 0xfffffffffff;
-        let mut g2 = h2.wrapping_add(g1 >> 44);
-        g1 &= 0xfffffffffff;
-        
-        let mask = (g2 >> 42).wrapping_sub(1);
+        let mut g1 = h1.wrapping_add(g0 >> 26);
+        g0 &= MASK;
+        let mut g2 = h2.wrapping_add(g1 >> 26);
+        g1 &= MASK;
+        let mut g3 = h3.wrapping_add(g2 >> 26);
+        g2 &= MASK;
+        let mut g4 = h4.wrapping_add(g3 >> 26).wrapping_sub(1 << 26);
+        g3 &= MASK;
+        
+        let mask = (g4 >> 31).wrapping_sub(1);
         g0 &= mask;
         g1 &= mask;
         g2 &= mask;
+        g3 &= mask;
+        g4 &= mask;
         let mask = !mask;
         h0 = (h0 & mask) | g0;
         h1 = (h1 & mask) | g1;
         h2 = (h2 & mask) | g2;
-        
-        h0 = h0.wrapping_add(s0);
-        h1 = h1.wrapping_add(s1).wrapping_add(h0 >> 32);
-        h2 = h2.wrapping_add(s2).wrapping_add(h1 >> 32);
-        let h3 = s3.wrapping_add(h2 >> 32);
-        
+        h3 = (h3 & mask) | g3;
+        h4 = (h4 & mask) | g4;
+        
+        // h + s mod 2^128
+        let words = [
+            h0 | (h1 << 26),
+            (h1 >> 6) | (h2 << 20),
+            (h2 >> 12) | (h3 << 14),
+            (h3 >> 18) | (h4 << 8),
+        ];
         let mut tag = Vec::with_capacity(16);
-        tag.extend_from_slice(&(h0 as u32).to_le_bytes());
-        tag.extend_from_slice(&(h1 as u32).to_le_bytes());
-        tag.extend_from_slice(&(h2 as u32).to_le_bytes());
-        tag.extend_from_slice(&(h3 as u32).to_le_bytes());
+        let mut carry = 0u64;
+        for (i, word) in words.iter().enumerate() {
+            let f = *word as u64 + le32(key, 16 + 4 * i) as u64 + carry;
+            tag.extend_from_slice(&(f as u32).to_le_bytes());
+            carry = f >> 32;
+        }
         
         Ok(tag)
     }
@@ -223,7 +225,9 @@ impl Mac for Poly1305 {
 
 pub fn get_mac(algorithm: MacAlgorithm, _provider: CryptoProvider) -> CryptoResult<Box<dyn Mac>> {
     match algorithm {
+        MacAlgorithm::HmacSHA1 => Ok(Box::new(Hmac::new(SHA1::new()))),
         MacAlgorithm::HmacSHA256 => Ok(Box::new(Hmac::new(SHA256::new()))),
+        MacAlgorithm::HmacSHA384 => Ok(Box::new(Hmac::new(SHA384::new()))),
         MacAlgorithm::HmacSHA512 => Ok(Box::new(Hmac::new(SHA512::new()))),
         MacAlgorithm::Poly1305 => Ok(Box::new(Poly1305::new())),
         _ => Err(CryptoError::UnsupportedAlgorithm),
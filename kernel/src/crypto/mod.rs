@@ -1,24 +1,24 @@
-#![no_std]
-
 pub mod cipher;
 pub mod hash;
 pub mod mac;
 pub mod aead;
 pub mod asymmetric;
+pub mod bignum;
 pub mod kdf;
 pub mod rng;
 pub mod hw_accel;
 pub mod errors;
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use alloc::string::String;
-use core::fmt;
+use crate::serial_println;
 
 pub use cipher::{SymmetricCipher, CipherAlgorithm, CipherMode};
 pub use hash::{HashAlgorithm, HashFunction};
 pub use mac::{MacAlgorithm, Mac};
 pub use aead::{AeadAlgorithm, Aead};
-pub use asymmetric::{PublicKey, PrivateKey, KeyPair, AsymmetricAlgorithm};
+pub use asymmetric::{PublicKey, PrivateKey, KeyPair, AsymmetricAlgorithm, SignatureScheme};
 pub use kdf::{KdfAlgorithm, KeyDerivation};
 pub use rng::{SecureRandom, RandomSource};
 pub use errors::CryptoError;
@@ -56,18 +56,21 @@ impl CryptoEngine {
         
         algos.push(String::from("AES-128-CBC"));
         algos.push(String::from("AES-256-CBC"));
+        algos.push(String::from("AES-128-XTS"));
+        algos.push(String::from("AES-256-XTS"));
         algos.push(String::from("AES-128-GCM"));
         algos.push(String::from("AES-256-GCM"));
         algos.push(String::from("ChaCha20"));
         algos.push(String::from("ChaCha20-Poly1305"));
         algos.push(String::from("SHA-256"));
+        algos.push(String::from("SHA-384"));
         algos.push(String::from("SHA-512"));
         algos.push(String::from("SHA3-256"));
         algos.push(String::from("SHA3-512"));
         algos.push(String::from("BLAKE2b"));
-        algos.push(String::from("BLAKE2s"));
         algos.push(String::from("RSA-2048"));
         algos.push(String::from("RSA-4096"));
+        algos.push(String::from("RSA-PSS"));
         algos.push(String::from("ECDSA-P256"));
         algos.push(String::from("Ed25519"));
         
@@ -103,18 +106,103 @@ impl CryptoEngine {
     }
 }
 
+// One-shot helpers used by the rest of the kernel. They always pick the
+// fastest implementation the CPU passed the self-test for.
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hash::SHA256::new().hash(data));
+    out
+}
+
+pub fn sha384(data: &[u8]) -> [u8; 48] {
+    let mut out = [0u8; 48];
+    out.copy_from_slice(&hash::SHA384::new().hash(data));
+    out
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    out.copy_from_slice(&hash::SHA512::new().hash(data));
+    out
+}
+
+pub fn sha3_256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&hash::SHA3_256::new().hash(data));
+    out
+}
+
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&hash::MD5::new().hash(data));
+    out
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    out.copy_from_slice(&hash::SHA1::new().hash(data));
+    out
+}
+
+pub fn hash(algorithm: HashAlgorithm, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    Ok(hash::get_hash(algorithm, CryptoProvider::Hybrid)?.hash(data))
+}
+
+pub fn hmac(algorithm: MacAlgorithm, key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    mac::get_mac(algorithm, CryptoProvider::Hybrid)?.compute(key, data)
+}
+
+/// Fill `buf` from the kernel CSPRNG
+pub fn random_bytes(buf: &mut [u8]) {
+    rng::fill_bytes(buf);
+}
+
+// XTS tweak for a data unit: the sector number, little endian
+fn sector_tweak(sector: u64) -> [u8; 16] {
+    let mut tweak = [0u8; 16];
+    tweak[..8].copy_from_slice(&sector.to_le_bytes());
+    tweak
+}
+
+/// AES-XTS encrypt one sector; `key` is two AES keys (32 or 64 bytes)
+pub fn aes_xts_encrypt(key: &[u8], sector: u64, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    cipher::xts_process(key, &sector_tweak(sector), data, true)
+}
+
+pub fn aes_xts_decrypt(key: &[u8], sector: u64, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    cipher::xts_process(key, &sector_tweak(sector), data, false)
+}
+
+/// AEAD encrypt; the result is ciphertext followed by the 16-byte tag
+pub fn seal(algorithm: AeadAlgorithm, key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    aead::get_aead(algorithm, CryptoProvider::Hybrid)?.encrypt(key, nonce, plaintext, aad)
+}
+
+pub fn open(algorithm: AeadAlgorithm, key: &[u8], nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    aead::get_aead(algorithm, CryptoProvider::Hybrid)?.decrypt(key, nonce, sealed, aad)
+}
+
+pub fn verify_signature(scheme: SignatureScheme, public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, CryptoError> {
+    asymmetric::verify_signature(scheme, public_key, data, signature)
+}
+
 pub fn init() {
-    log::info!("Initializing kernel crypto subsystem");
-    
+    serial_println!("[CRYPTO] Initializing kernel crypto subsystem");
+
     if hw_accel::detect_hardware_crypto() {
-        log::info!("Hardware crypto acceleration available");
         hw_accel::init_hardware_crypto();
     }
-    
+
     rng::init_random_subsystem();
-    
-    log::info!("Crypto subsystem initialized");
+
+    serial_println!(
+        "[CRYPTO] Ready (AES-NI: {}, CLMUL: {}, SHA-NI: {})",
+        hw_accel::use_aes_ni(),
+        hw_accel::use_clmul(),
+        hw_accel::use_sha_ni()
+    );
 }
 
 #[cfg(test)]
-mod tests;
\ No newline at end of file
+mod tests;
@@ -1,9 +1,16 @@
-#![no_std]
+// Entropy sources and the kernel CSPRNG
+//
+// The global generator is ChaCha20 keyed from RDSEED (RDRAND when RDSEED is
+// missing or exhausted) together with a pool of interrupt timing jitter. It is
+// rekeyed from fresh entropy every RESEED_INTERVAL interrupts.
 
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
 use super::CryptoProvider;
 use super::hash::{HashFunction, SHA256};
+use super::hw_accel;
 
 pub trait SecureRandom: Send + Sync {
     fn generate(&self, length: usize) -> Vec<u8>;
@@ -245,30 +252,65 @@ impl HardwareRng {
     
     #[cfg(target_arch = "x86_64")]
     fn rdrand(&self) -> Option<u64> {
-        let mut value: u64;
-        let success: u8;
+        if !hw_accel::features().rdrand {
+            return None;
+        }
         
-        unsafe {
-            core::arch::asm!(
-                "rdrand {}",
-                "setc {}",
-                out(reg) value,
-                out(reg_byte) success,
-                options(nomem, nostack)
-            );
+        // The DRBG can transiently run dry; Intel recommends ten retries
+        for _ in 0..10 {
+            let value: u64;
+            let success: u8;
+            unsafe {
+                core::arch::asm!(
+                    "rdrand {}",
+                    "setc {}",
+                    out(reg) value,
+                    out(reg_byte) success,
+                    options(nomem, nostack)
+                );
+            }
+            if success != 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+    
+    #[cfg(target_arch = "x86_64")]
+    fn rdseed(&self) -> Option<u64> {
+        if !hw_accel::features().rdseed {
+            return None;
         }
         
-        if success != 0 {
-            Some(value)
-        } else {
-            None
+        for _ in 0..100 {
+            let value: u64;
+            let success: u8;
+            unsafe {
+                core::arch::asm!(
+                    "rdseed {}",
+                    "setc {}",
+                    out(reg) value,
+                    out(reg_byte) success,
+                    options(nomem, nostack)
+                );
+            }
+            if success != 0 {
+                return Some(value);
+            }
+            core::hint::spin_loop();
         }
+        None
     }
     
     #[cfg(not(target_arch = "x86_64"))]
     fn rdrand(&self) -> Option<u64> {
         None
     }
+    
+    #[cfg(not(target_arch = "x86_64"))]
+    fn rdseed(&self) -> Option<u64> {
+        None
+    }
 }
 
 impl RandomSource for HardwareRng {
@@ -276,7 +318,7 @@ impl RandomSource for HardwareRng {
         let mut entropy = Vec::with_capacity(length);
         
         while entropy.len() < length {
-            if let Some(value) = self.rdrand() {
+            if let Some(value) = self.rdseed().or_else(|| self.rdrand()) {
                 let bytes = value.to_le_bytes();
                 let remaining = length - entropy.len();
                 let to_copy = core::cmp::min(remaining, 8);
@@ -293,17 +335,77 @@ impl RandomSource for HardwareRng {
     }
 }
 
-static mut GLOBAL_RNG: Option<ChaCha20Rng> = None;
+const RESEED_INTERVAL: u64 = 1024;
 
-pub fn init_random_subsystem() {
-    let hw_rng = HardwareRng::new();
-    let initial_entropy = hw_rng.get_entropy(32);
+static JITTER_POOL: AtomicU64 = AtomicU64::new(0);
+static JITTER_EVENTS: AtomicU64 = AtomicU64::new(0);
+static LAST_RESEED: AtomicU64 = AtomicU64::new(0);
+
+/// Fold the arrival time of an interrupt into the jitter pool. Safe to call
+/// from interrupt context: one TSC read and two atomic operations.
+pub fn add_interrupt_entropy(vector: u8) {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let events = JITTER_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let sample = tsc.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ vector as u64;
+    JITTER_POOL.fetch_xor(sample.rotate_left((events % 64) as u32), Ordering::Relaxed);
+}
+
+// 32 bytes of seed material: hardware entropy, the jitter pool and the TSC
+// condensed through SHA-256
+fn gather_seed() -> Vec<u8> {
+    let mut material = HardwareRng::new().get_entropy(64);
+    material.extend_from_slice(&JITTER_POOL.load(Ordering::Relaxed).to_le_bytes());
+    material.extend_from_slice(&JITTER_EVENTS.load(Ordering::Relaxed).to_le_bytes());
+    material.extend_from_slice(&unsafe { core::arch::x86_64::_rdtsc() }.to_le_bytes());
+    SHA256::new().hash(&material)
+}
+
+lazy_static! {
+    static ref GLOBAL_RNG: ChaCha20Rng = ChaCha20Rng::new(&gather_seed());
+}
+
+fn maybe_reseed() {
+    let events = JITTER_EVENTS.load(Ordering::Relaxed);
+    let last = LAST_RESEED.load(Ordering::Relaxed);
+    if events.wrapping_sub(last) >= RESEED_INTERVAL
+        && LAST_RESEED
+            .compare_exchange(last, events, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        GLOBAL_RNG.reseed(&gather_seed());
+    }
+}
+
+/// Fill `buf` from the kernel CSPRNG
+pub fn fill_bytes(buf: &mut [u8]) {
+    maybe_reseed();
+    let bytes = GLOBAL_RNG.generate(buf.len());
+    buf.copy_from_slice(&bytes);
+}
+
+// Handle on the global generator
+pub struct SystemRng;
+
+impl SecureRandom for SystemRng {
+    fn generate(&self, length: usize) -> Vec<u8> {
+        maybe_reseed();
+        GLOBAL_RNG.generate(length)
+    }
     
-    unsafe {
-        GLOBAL_RNG = Some(ChaCha20Rng::new(&initial_entropy));
+    fn generate_range(&self, min: u64, max: u64) -> u64 {
+        maybe_reseed();
+        GLOBAL_RNG.generate_range(min, max)
+    }
+    
+    fn reseed(&self, entropy: &[u8]) {
+        GLOBAL_RNG.reseed(entropy);
     }
 }
 
+pub fn init_random_subsystem() {
+    lazy_static::initialize(&GLOBAL_RNG);
+}
+
 pub fn get_secure_random(provider: CryptoProvider) -> Box<dyn SecureRandom> {
     match provider {
         CryptoProvider::Hardware => {
@@ -311,13 +413,6 @@ pub fn get_secure_random(provider: CryptoProvider) -> Box<dyn SecureRandom> {
             let entropy = hw_rng.get_entropy(32);
             Box::new(ChaCha20Rng::new(&entropy))
         }
-        _ => {
-            unsafe {
-                if GLOBAL_RNG.is_none() {
-                    init_random_subsystem();
-                }
-                Box::new(ChaCha20Rng::new(&[0u8; 32]))
-            }
-        }
+        _ => Box::new(SystemRng),
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
 #![cfg(test)]
 
 use super::*;
+use super::asymmetric::AsymmetricCrypto;
 use alloc::vec;
 
 #[test]
@@ -150,4 +151,150 @@ fn test_rsa_encryption() {
     let decrypted = rsa.decrypt(&private_key, &ciphertext).unwrap();
     
     assert_eq!(decrypted, plaintext);
-}
\ No newline at end of file
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+fn test_aes_fips197_vector() {
+    let key = cipher::AesKey::new(&unhex("000102030405060708090a0b0c0d0e0f")).unwrap();
+    let mut block = [0u8; 16];
+    block.copy_from_slice(&unhex("00112233445566778899aabbccddeeff"));
+
+    key.encrypt_block(&mut block);
+    assert_eq!(block.to_vec(), unhex("69c4e0d86a7b0430d8cdb78070b4c55a"));
+    key.decrypt_block(&mut block);
+    assert_eq!(block.to_vec(), unhex("00112233445566778899aabbccddeeff"));
+}
+
+#[test]
+fn test_aes_xts_ciphertext_stealing() {
+    let key: Vec<u8> = (0..64).collect();
+    let plaintext: Vec<u8> = (0..37).collect();
+
+    let ciphertext = aes_xts_encrypt(&key, 5, &plaintext).unwrap();
+    assert_eq!(ciphertext, unhex("f87ca2f29b117c1b024a6ec8e8c5994efd1452657680c2a85b78afd9bbfcb33e76f7d16b43"));
+    assert_eq!(aes_xts_decrypt(&key, 5, &ciphertext).unwrap(), plaintext);
+}
+
+#[test]
+fn test_aes_gcm_vector() {
+    let sealed = seal(AeadAlgorithm::AesGcm128, &[0u8; 16], &[0u8; 12], &[0u8; 16], &[]).unwrap();
+    assert_eq!(sealed, unhex("0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf"));
+
+    let mut tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert_eq!(open(AeadAlgorithm::AesGcm128, &[0u8; 16], &[0u8; 12], &tampered, &[]), Err(CryptoError::AuthenticationFailed));
+}
+
+#[test]
+fn test_aes_ccm_rfc3610_vector() {
+    let ccm = aead::AesCcm::new(16, 8, 13);
+    let key: Vec<u8> = (0xc0..0xd0).collect();
+    let nonce = unhex("00000003020100a0a1a2a3a4a5");
+    let aad: Vec<u8> = (0x00..0x08).collect();
+    let plaintext: Vec<u8> = (0x08..0x1f).collect();
+
+    let sealed = ccm.encrypt(&key, &nonce, &plaintext, &aad).unwrap();
+    assert_eq!(sealed, unhex("588c979a61c663d2f066d0c2c0f989806d5f6b61dac38417e8d12cfdf926e0"));
+    assert_eq!(ccm.decrypt(&key, &nonce, &sealed, &aad).unwrap(), plaintext);
+
+    let mut tampered = sealed.clone();
+    tampered[0] ^= 1;
+    assert_eq!(ccm.decrypt(&key, &nonce, &tampered, &aad), Err(CryptoError::AuthenticationFailed));
+}
+
+#[test]
+fn test_aes_key_wrap_rfc3394_vector() {
+    let kek = unhex("000102030405060708090a0b0c0d0e0f");
+    let key = unhex("00112233445566778899aabbccddeeff");
+
+    let wrapped = cipher::aes_key_wrap(&kek, &key).unwrap();
+    assert_eq!(wrapped, unhex("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5"));
+    assert_eq!(cipher::aes_key_unwrap(&kek, &wrapped).unwrap(), key);
+
+    let mut tampered = wrapped.clone();
+    tampered[8] ^= 1;
+    assert_eq!(cipher::aes_key_unwrap(&kek, &tampered), Err(CryptoError::AuthenticationFailed));
+}
+
+#[test]
+fn test_wpa_psk_vector() {
+    // IEEE 802.11 annex J: the PSK for passphrase "password" on SSID "IEEE"
+    let kdf = CryptoEngine::new().get_kdf(KdfAlgorithm::PBKDF2SHA1).unwrap();
+    let psk = kdf.derive(b"password", b"IEEE", 4096, 32).unwrap();
+    assert_eq!(psk, unhex("f42c6fc52df0ebef9ebb4b90b38a5f902e83fe1b135a70e23aed762e9710a12e"));
+}
+
+#[test]
+fn test_poly1305_rfc8439_vector() {
+    let key = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+    let tag = mac::Poly1305::new().compute(&key, b"Cryptographic Forum Research Group").unwrap();
+    assert_eq!(tag, unhex("a8061dc1305136c6c22b8baf0c0127a9"));
+}
+
+#[test]
+fn test_sha_vectors() {
+    assert_eq!(sha256(b"abc").to_vec(), unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+    assert_eq!(
+        sha384(b"abc").to_vec(),
+        unhex("cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed8086072ba1e7cc2358baeca134c825a7")
+    );
+    assert_eq!(sha3_256(b"abc").to_vec(), unhex("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"));
+    assert_eq!(md5(b"abc").to_vec(), unhex("900150983cd24fb0d6963f7d28e17f72"));
+    assert_eq!(sha1(b"abc").to_vec(), unhex("a9993e364706816aba3e25717850c26c9cd0d89d"));
+}
+
+const RSA_N: &str = "caa0fc02ff26f31c18ff53970b24fd0b24c4a7af6b73e1f4417565a6b4809f2302b85f212cb66521595a5bc49e357e6b7a056be7cc221689c3edda7f6ab3b3c1fe514391a9ff240ca6ab8fc0dbfbea44f8b86fa34a8ebd015f96d8a3ee43ba73f2710cbcd7b04570cd0541b54622b73779423f3a23e293d69a14eed2fc98ce05";
+
+#[test]
+fn test_rsa_signature_verification() {
+    let key = asymmetric::rsa_public_key(&unhex(RSA_N), &[0x01, 0x00, 0x01]);
+    let message = b"signed kernel image";
+    let pkcs1 = unhex("416a70ef3c5fccbf1d8f8be9612aa20279a3af2acfa397b4e841fb7609f2783b1f12c0c65b0e757c134945d5ae17a33ef0eb734c3ce344f3354cfedfc0fcf0dfd27266b6b4f7efa386e354b5a803bc6e344830f962585ebc9a7e5447d20db3ed17fd7e1acd9008407d8bd2bee20ceea31114354d63a1674098978433ce67216d");
+    let pss = unhex("7327163ef00bdfa3db9cf2f24f36ff08633fbc1c7c231a5f3c873f7dd79486dd085e1e42b1eb57b82d6f3f3b49aea275494e16e39d386c5b12f9691b9f6dcc807b509e7cb31e312a83d2c79659a02727a5d045a27beeffc4379c2a8307cf9d0fabbf73c1b72af09b85ff1428a07ff7046ec91656fe4676421d96711d52d827a5");
+
+    assert!(verify_signature(SignatureScheme::RsaPkcs1Sha256, &key, message, &pkcs1).unwrap());
+    assert!(verify_signature(SignatureScheme::RsaPssSha256, &key, message, &pss).unwrap());
+    assert!(!verify_signature(SignatureScheme::RsaPkcs1Sha256, &key, b"tampered image", &pkcs1).unwrap());
+    assert!(!verify_signature(SignatureScheme::RsaPssSha256, &key, b"tampered image", &pss).unwrap());
+}
+
+#[test]
+fn test_ecdsa_p256_verification() {
+    let key = unhex("0402a1b44bc467ec3bd1c60b4bd5fd57942399b41cccce6e70ebea091c1c0b10388a7ae6f8fa4208ec0dc863381c3a0f63323548e4cfc79bde1c29436af86a132b");
+    let signature = unhex("304402203a3c23cccebe327cc75aa5b65086010bf259f93d68595f315745d677c933b15b02200fc74f618a5ac61438d73df1bb9f12d7dad75b06bfb2e5f61f493f66d97f3186");
+
+    assert!(verify_signature(SignatureScheme::EcdsaP256Sha256, &key, b"signed kernel image", &signature).unwrap());
+    assert!(!verify_signature(SignatureScheme::EcdsaP256Sha256, &key, b"tampered image", &signature).unwrap());
+}
+
+#[test]
+fn test_ed25519_rfc8032_vector() {
+    let seed = unhex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+    let public_key = unhex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+    let expected = unhex("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b");
+
+    let mut private_key = seed.clone();
+    private_key.extend_from_slice(&public_key);
+    let signature = asymmetric::Ed25519::new().sign(&private_key, b"").unwrap();
+
+    assert_eq!(signature, expected);
+    assert!(verify_signature(SignatureScheme::Ed25519, &public_key, b"", &signature).unwrap());
+    assert!(!verify_signature(SignatureScheme::Ed25519, &public_key, b"x", &signature).unwrap());
+}
+
+#[test]
+fn test_x25519_rfc7748_vector() {
+    let scalar: [u8; 32] = unhex("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4").try_into().unwrap();
+    let u: [u8; 32] = unhex("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c").try_into().unwrap();
+    let expected = unhex("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+
+    assert_eq!(asymmetric::x25519(&scalar, &u).unwrap().to_vec(), expected);
+
+    let (a_private, a_public) = asymmetric::x25519_keypair();
+    let (b_private, b_public) = asymmetric::x25519_keypair();
+    assert_eq!(asymmetric::x25519(&a_private, &b_public), asymmetric::x25519(&b_private, &a_public));
+}
@@ -1,6 +1,7 @@
 // Interactive Kernel Debugger (KDB)
 // Provides an interactive debugging shell within the kernel
 
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
@@ -130,6 +131,7 @@ impl KernelDebugger {
         commands.insert("threads".to_string(), cmd_threads as CommandHandler);
         commands.insert("locks".to_string(), cmd_locks as CommandHandler);
         commands.insert("irq".to_string(), cmd_irq as CommandHandler);
+        commands.insert("dump".to_string(), cmd_dump as CommandHandler);
     }
     
     pub fn enter(&self, reason: &str) {
@@ -334,6 +336,7 @@ fn cmd_help(_args: &[&str]) -> Result<(), String> {
     crate::serial_println!("  threads            - List threads");
     crate::serial_println!("  locks              - Show lock status");
     crate::serial_println!("  irq                - IRQ statistics");
+    crate::serial_println!("  dump [info|send]   - Last crash dump; resend it over serial");
     Ok(())
 }
 
@@ -651,6 +654,24 @@ fn cmd_irq(_args: &[&str]) -> Result<(), String> {
     Ok(())
 }
 
+fn cmd_dump(args: &[&str]) -> Result<(), String> {
+    match args.first().copied() {
+        Some("send") => super::kdump::resend_last_dump(),
+        Some("info") | None => {
+            let analysis = super::kdump::analyze_last_dump()?;
+            crate::serial_println!("Last crash dump:");
+            crate::serial_println!("  Reason: {}", analysis.panic_reason);
+            crate::serial_println!("  RIP: {:#018x}  CR2: {:#018x}",
+                analysis.faulting_instruction, analysis.faulting_address);
+            for frame in &analysis.stack_trace {
+                crate::serial_println!("  {}", frame);
+            }
+            Ok(())
+        }
+        Some(other) => Err(format!("Unknown dump command: {} (use info or send)", other)),
+    }
+}
+
 // Helper functions
 fn parse_address(s: &str) -> Result<u64, String> {
     if s.starts_with("0x") || s.starts_with("0X") {
@@ -684,8 +705,9 @@ pub fn handle_breakpoint(rip: u64) {
         if bp.address == rip - 1 {  // INT3 is 1 byte
             bp.hit_count += 1;
             crate::serial_println!("Breakpoint {} hit at {:#x}", bp.id, bp.address);
+            let id = bp.id;
             drop(breakpoints);
-            KDB.enter(&format!("Breakpoint {} hit", bp.id));
+            KDB.enter(&format!("Breakpoint {} hit", id));
             return;
         }
     }
@@ -1,55 +1,84 @@
 // Kernel Crash Dump System (kdump)
 // Generates and saves crash dumps for post-mortem analysis
+//
+// A dump is a sequential little-endian stream:
+//
+//   header     "KDUMPV02", version, dump id, timestamp, total size, section
+//              count, CPU count, crashing CPU, dump type
+//   directory  one (kind, count, offset, size) entry per section
+//   sections   panic message, register state of every CPU, the tail of the
+//              kernel log, loaded modules, memory ranges
+//   trailer    "KDUMPEND" and the CRC-32 of everything before it
+//
+// Dumps go to a reserved disk partition and/or a serial port using the
+// resumable protocol in `serial_link`. tools/kdump/kdump2dmp.rs receives
+// dumps and converts them into WinDbg-compatible minidumps.
 
 use alloc::vec::Vec;
-use alloc::string::String;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use alloc::vec;
+use alloc::string::{String, ToString};
+use alloc::format;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
-use x86_64::VirtAddr;
 use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
 
-// Crash dump header
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct CrashDumpHeader {
-    pub signature: [u8; 8],        // "KDUMPV01"
-    pub version: u32,
-    pub header_size: u32,
-    pub timestamp: u64,
-    pub panic_message_offset: u64,
-    pub panic_message_length: u64,
-    pub cpu_count: u32,
-    pub current_cpu: u32,
-    pub physical_memory_size: u64,
-    pub dump_type: DumpType,
-    pub compression: CompressionType,
-    pub checksum: u32,
-}
+pub const DUMP_SIGNATURE: &[u8; 8] = b"KDUMPV02";
+pub const TRAILER_SIGNATURE: &[u8; 8] = b"KDUMPEND";
+pub const DUMP_VERSION: u32 = 2;
+
+const HEADER_SIZE: usize = 64;
+const DIRECTORY_ENTRY_SIZE: usize = 24;
+const TRAILER_SIZE: usize = 16;
+const CPU_RECORD_SIZE: usize = 200;
+const MEMORY_RECORD_HEADER_SIZE: usize = 32;
+
+/// MBR partition type of a partition reserved for crash dumps
+pub const DUMP_PARTITION_TYPE: u8 = 0x7f;
+
+// Stack saved around every CPU's stack pointer, in all dump types
+const STACK_CAPTURE_SIZE: u64 = 16 * 1024;
+// How long the crashing CPU waits for the others to save their registers
+const CPU_SAVE_TIMEOUT_SPINS: u32 = 10_000_000;
+const PAGE_SIZE: u64 = 4096;
 
 #[repr(u32)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DumpType {
-    MiniDump = 1,      // Essential information only
-    KernelDump = 2,    // Kernel memory only
-    FullDump = 3,      // Complete memory dump
-    LiveDump = 4,      // Live system snapshot
+    MiniDump = 1,      // Registers, stacks, kernel log and module list
+    KernelDump = 2,    // Mini dump plus the configured physical memory ranges
+    FullDump = 3,      // Mini dump plus all physical memory
+    LiveDump = 4,      // Kernel dump taken without stopping the other CPUs
+}
+
+impl DumpType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "mini" => Some(DumpType::MiniDump),
+            "kernel" => Some(DumpType::KernelDump),
+            "full" => Some(DumpType::FullDump),
+            "live" => Some(DumpType::LiveDump),
+            _ => None,
+        }
+    }
 }
 
 #[repr(u32)]
-#[derive(Debug, Clone, Copy)]
-pub enum CompressionType {
-    None = 0,
-    Gzip = 1,
-    Lz4 = 2,
-    Zstd = 3,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    PanicMessage = 1,
+    CpuContexts = 2,
+    KernelLog = 3,
+    Modules = 4,
+    Memory = 5,
 }
 
 // CPU context saved in dump
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CpuContext {
     pub cpu_id: u32,
+    pub apic_id: u32,
     pub rax: u64,
     pub rbx: u64,
     pub rcx: u64,
@@ -80,6 +109,28 @@ pub struct CpuContext {
     pub ss: u16,
 }
 
+impl CpuContext {
+    // Fixed-size record: ids, 22 quadword registers, 6 selectors, padding
+    fn to_record(&self) -> [u8; CPU_RECORD_SIZE] {
+        let mut record = [0u8; CPU_RECORD_SIZE];
+        record[0..4].copy_from_slice(&self.cpu_id.to_le_bytes());
+        record[4..8].copy_from_slice(&self.apic_id.to_le_bytes());
+        let registers = [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+            self.rip, self.rflags, self.cr0, self.cr2, self.cr3, self.cr4,
+        ];
+        for (i, value) in registers.iter().enumerate() {
+            record[8 + i * 8..16 + i * 8].copy_from_slice(&value.to_le_bytes());
+        }
+        let selectors = [self.cs, self.ds, self.es, self.fs, self.gs, self.ss];
+        for (i, value) in selectors.iter().enumerate() {
+            record[184 + i * 2..186 + i * 2].copy_from_slice(&value.to_le_bytes());
+        }
+        record
+    }
+}
+
 // Memory region descriptor
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -102,355 +153,624 @@ pub enum MemoryRegionType {
     Hardware = 7,
 }
 
+#[derive(Debug, Clone)]
+pub struct ModuleRecord {
+    pub name: String,
+    pub base: u64,
+    pub size: u64,
+}
+
+/// Where dumps go and what they contain
+#[derive(Debug, Clone)]
+pub struct DumpConfig {
+    pub dump_type: DumpType,
+    /// (device number, partition number) of the dump partition
+    pub partition: Option<(u32, u32)>,
+    /// I/O base of the serial port dumps are streamed over
+    pub serial_port: Option<u16>,
+    /// Memory included in kernel dumps, as (start, size) in the identity
+    /// mapping of physical memory
+    pub memory_ranges: Vec<(u64, u64)>,
+}
+
+impl Default for DumpConfig {
+    fn default() -> Self {
+        Self {
+            dump_type: DumpType::KernelDump,
+            partition: None,
+            serial_port: Some(serial_link::COM2),
+            memory_ranges: Vec::new(),
+        }
+    }
+}
+
+/// Everything in a dump except memory contents, which are read from RAM
+/// while the dump is written so that no copy of them has to fit in the heap
+#[derive(Debug, Clone)]
+pub struct DumpImage {
+    pub dump_id: u64,
+    pub timestamp: u64,
+    pub dump_type: DumpType,
+    pub crashing_cpu: u32,
+    pub panic_message: String,
+    pub cpus: Vec<CpuContext>,
+    pub kernel_log: Vec<u8>,
+    pub modules: Vec<ModuleRecord>,
+    pub memory: Vec<MemoryRegion>,
+}
+
+impl DumpImage {
+    fn module_section(&self) -> Vec<u8> {
+        let mut section = Vec::new();
+        for module in &self.modules {
+            section.extend_from_slice(&module.base.to_le_bytes());
+            section.extend_from_slice(&module.size.to_le_bytes());
+            section.extend_from_slice(&(module.name.len() as u32).to_le_bytes());
+            section.extend_from_slice(module.name.as_bytes());
+        }
+        section
+    }
+
+    fn memory_section_size(&self) -> u64 {
+        self.memory.iter().map(|r| MEMORY_RECORD_HEADER_SIZE as u64 + r.size).sum()
+    }
+
+    /// Size of the whole dump stream in bytes
+    pub fn total_size(&self) -> u64 {
+        let sections = self.panic_message.len()
+            + self.cpus.len() * CPU_RECORD_SIZE
+            + self.kernel_log.len()
+            + self.module_section().len();
+        (HEADER_SIZE + 5 * DIRECTORY_ENTRY_SIZE + sections + TRAILER_SIZE) as u64
+            + self.memory_section_size()
+    }
+
+    /// Stream the dump into a sink
+    pub fn write_to(&self, sink: &mut dyn DumpSink) -> Result<(), String> {
+        let modules = self.module_section();
+        let sections = [
+            (SectionKind::PanicMessage, 1, self.panic_message.len() as u64),
+            (SectionKind::CpuContexts, self.cpus.len(), (self.cpus.len() * CPU_RECORD_SIZE) as u64),
+            (SectionKind::KernelLog, 1, self.kernel_log.len() as u64),
+            (SectionKind::Modules, self.modules.len(), modules.len() as u64),
+            (SectionKind::Memory, self.memory.len(), self.memory_section_size()),
+        ];
+
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(DUMP_SIGNATURE);
+        header[8..12].copy_from_slice(&DUMP_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&self.dump_id.to_le_bytes());
+        header[24..32].copy_from_slice(&self.timestamp.to_le_bytes());
+        header[32..40].copy_from_slice(&self.total_size().to_le_bytes());
+        header[40..44].copy_from_slice(&(sections.len() as u32).to_le_bytes());
+        header[44..48].copy_from_slice(&(self.cpus.len() as u32).to_le_bytes());
+        header[48..52].copy_from_slice(&self.crashing_cpu.to_le_bytes());
+        header[52..56].copy_from_slice(&(self.dump_type as u32).to_le_bytes());
+
+        let mut out = DumpStream { sink, offset: 0, crc: Crc32::new() };
+        out.put(&header)?;
+
+        let mut offset = (HEADER_SIZE + sections.len() * DIRECTORY_ENTRY_SIZE) as u64;
+        for (kind, count, size) in sections {
+            let mut entry = [0u8; DIRECTORY_ENTRY_SIZE];
+            entry[0..4].copy_from_slice(&(kind as u32).to_le_bytes());
+            entry[4..8].copy_from_slice(&(count as u32).to_le_bytes());
+            entry[8..16].copy_from_slice(&offset.to_le_bytes());
+            entry[16..24].copy_from_slice(&size.to_le_bytes());
+            out.put(&entry)?;
+            offset += size;
+        }
+
+        out.put(self.panic_message.as_bytes())?;
+        for cpu in &self.cpus {
+            out.put(&cpu.to_record())?;
+        }
+        out.put(&self.kernel_log)?;
+        out.put(&modules)?;
+
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        for region in &self.memory {
+            let mut record = [0u8; MEMORY_RECORD_HEADER_SIZE];
+            record[0..8].copy_from_slice(&region.start_address.to_le_bytes());
+            // Physical memory is identity mapped
+            record[8..16].copy_from_slice(&region.start_address.to_le_bytes());
+            record[16..24].copy_from_slice(&region.size.to_le_bytes());
+            record[24..28].copy_from_slice(&(region.region_type as u32).to_le_bytes());
+            record[28..32].copy_from_slice(&region.flags.to_le_bytes());
+            out.put(&record)?;
+
+            let mut address = region.start_address;
+            let end = region.start_address + region.size;
+            while address < end {
+                let chunk = (PAGE_SIZE - address % PAGE_SIZE).min(end - address) as usize;
+                read_memory(address, &mut page[..chunk]);
+                out.put(&page[..chunk])?;
+                address += chunk as u64;
+            }
+        }
+
+        let crc = out.crc.finish();
+        let mut trailer = [0u8; TRAILER_SIZE];
+        trailer[0..8].copy_from_slice(TRAILER_SIGNATURE);
+        trailer[8..12].copy_from_slice(&crc.to_le_bytes());
+        out.put(&trailer)?;
+        out.sink.finish()
+    }
+}
+
+/// Destination of a dump stream. Writes arrive in order.
+pub trait DumpSink {
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), String>;
+
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+struct DumpStream<'a> {
+    sink: &'a mut dyn DumpSink,
+    offset: u64,
+    crc: Crc32,
+}
+
+impl DumpStream<'_> {
+    fn put(&mut self, data: &[u8]) -> Result<(), String> {
+        self.crc.update(data);
+        self.sink.write(self.offset, data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by the dump trailer and serial frames
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// Copy memory that does not cross a page boundary; unmapped pages read as zeros
+fn read_memory(address: u64, buffer: &mut [u8]) {
+    if super::is_mapped(address) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len());
+        }
+    } else {
+        buffer.fill(0);
+    }
+}
+
+/// Writes a dump to a partition, in 64 KiB blocks from the partition start
+pub struct PartitionSink {
+    device: u32,
+    start: u64,
+    length: u64,
+    buffer: Vec<u8>,
+    buffer_offset: u64,
+}
+
+impl PartitionSink {
+    const BLOCK_SIZE: usize = 64 * 1024;
+
+    pub fn open(device: u32, partition: u32) -> Result<Self, String> {
+        let info = crate::drivers::storage::get_storage_partitions(device)
+            .into_iter()
+            .find(|p| p.partition_number == partition)
+            .ok_or_else(|| format!("No partition {} on device {}", partition, device))?;
+        Ok(Self {
+            device,
+            start: info.starting_offset,
+            length: info.partition_length,
+            buffer: Vec::with_capacity(Self::BLOCK_SIZE),
+            buffer_offset: 0,
+        })
+    }
+
+    fn flush_block(&mut self) -> Result<(), String> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        if self.buffer_offset + self.buffer.len() as u64 > self.length {
+            return Err("Dump does not fit in the dump partition".into());
+        }
+        crate::drivers::storage::write_storage_device(
+            self.device,
+            self.start + self.buffer_offset,
+            &self.buffer,
+        )
+        .map_err(|status| format!("Dump partition write failed: {:?}", status))?;
+        self.buffer_offset += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl DumpSink for PartitionSink {
+    fn write(&mut self, _offset: u64, mut data: &[u8]) -> Result<(), String> {
+        while !data.is_empty() {
+            let room = Self::BLOCK_SIZE - self.buffer.len();
+            let take = room.min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == Self::BLOCK_SIZE {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        // Pad the last block to whole sectors
+        let padded = (self.buffer.len() + 511) & !511;
+        self.buffer.resize(padded, 0);
+        self.flush_block()?;
+        let _ = crate::drivers::storage::flush_storage_device(self.device);
+        Ok(())
+    }
+}
+
 // Crash dump manager
 pub struct CrashDumpManager {
     enabled: AtomicBool,
     dump_in_progress: AtomicBool,
     dump_count: AtomicU64,
-    reserved_memory: Option<ReservedDumpArea>,
-    dump_type: Mutex<DumpType>,
-    compression: Mutex<CompressionType>,
-}
-
-struct ReservedDumpArea {
-    physical_address: u64,
-    virtual_address: VirtAddr,
-    size: usize,
+    config: Mutex<DumpConfig>,
+    last_dump: Mutex<Option<DumpImage>>,
 }
 
 lazy_static! {
     pub static ref CRASH_DUMP: CrashDumpManager = CrashDumpManager::new();
+
+    // Register state saved by each CPU when it is stopped for a dump
+    static ref SAVED_CPUS: Vec<Mutex<Option<CpuContext>>> =
+        (0..crate::smp::MAX_CPUS).map(|_| Mutex::new(None)).collect();
 }
 
+static CPUS_SAVED: AtomicU32 = AtomicU32::new(0);
+
 impl CrashDumpManager {
     pub fn new() -> Self {
         Self {
             enabled: AtomicBool::new(true),
             dump_in_progress: AtomicBool::new(false),
             dump_count: AtomicU64::new(0),
-            reserved_memory: None,
-            dump_type: Mutex::new(DumpType::KernelDump),
-            compression: Mutex::new(CompressionType::None),
-        }
-    }
-    
-    pub fn reserve_dump_memory(&mut self, size: usize) -> Result<(), String> {
-        // Reserve memory for crash dumps (would allocate from physical memory)
-        // This memory should be preserved across kexec for kdump kernel
-        let phys_addr = 0x10000000;  // Example address
-        let virt_addr = VirtAddr::new(phys_addr);
-        
-        self.reserved_memory = Some(ReservedDumpArea {
-            physical_address: phys_addr,
-            virtual_address: virt_addr,
-            size,
-        });
-        
-        crate::serial_println!("[KDUMP] Reserved {} MB at {:#x} for crash dumps",
-            size / (1024 * 1024), phys_addr);
-        Ok(())
+            config: Mutex::new(DumpConfig::default()),
+            last_dump: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> DumpConfig {
+        self.config.lock().clone()
     }
-    
+
     pub fn create_dump(&self, panic_info: &core::panic::PanicInfo) -> Result<(), String> {
+        self.create_dump_with_message(format!("{}", panic_info))
+    }
+
+    pub fn create_dump_with_message(&self, message: String) -> Result<(), String> {
         // Check if already dumping (prevent recursive dumps)
         if self.dump_in_progress.swap(true, Ordering::SeqCst) {
             return Err("Dump already in progress".into());
         }
-        
+        if !self.enabled.load(Ordering::Relaxed) {
+            self.dump_in_progress.store(false, Ordering::SeqCst);
+            return Err("Crash dumps are disabled".into());
+        }
+
+        // A panic while the configuration was being changed leaves it locked
+        let config = match self.config.try_lock() {
+            Some(config) => config.clone(),
+            None => DumpConfig::default(),
+        };
+
         let dump_id = self.dump_count.fetch_add(1, Ordering::SeqCst);
-        crate::serial_println!("[KDUMP] Creating crash dump #{}", dump_id);
-        
-        // Collect CPU context
-        let cpu_context = self.capture_cpu_context();
-        
-        // Create dump header
-        let header = self.create_dump_header(panic_info);
-        
-        // Determine what to dump based on dump type
-        let dump_type = *self.dump_type.lock();
-        
-        match dump_type {
-            DumpType::MiniDump => self.create_mini_dump(&header, &cpu_context, panic_info),
-            DumpType::KernelDump => self.create_kernel_dump(&header, &cpu_context, panic_info),
-            DumpType::FullDump => self.create_full_dump(&header, &cpu_context, panic_info),
-            DumpType::LiveDump => self.create_live_dump(&header, &cpu_context),
-        }?;
-        
-        // Write dump to destination
-        self.write_dump_to_storage(dump_id)?;
-        
-        crate::serial_println!("[KDUMP] Crash dump #{} completed", dump_id);
-        
+        crate::serial_println!("[KDUMP] Creating {:?} #{}", config.dump_type, dump_id);
+
+        let image = self.collect(dump_id, message, &config);
+        let result = self.write_dump(&image, &config);
+
+        if let Some(mut last) = self.last_dump.try_lock() {
+            *last = Some(image);
+        }
         self.dump_in_progress.store(false, Ordering::SeqCst);
+
+        result?;
+        crate::serial_println!("[KDUMP] Crash dump #{} completed", dump_id);
         Ok(())
     }
-    
-    fn capture_cpu_context(&self) -> CpuContext {
-        let mut context = CpuContext {
-            cpu_id: 0,  // Would get actual CPU ID
-            rax: 0, rbx: 0, rcx: 0, rdx: 0,
-            rsi: 0, rdi: 0, rbp: 0, rsp: 0,
-            r8: 0, r9: 0, r10: 0, r11: 0,
-            r12: 0, r13: 0, r14: 0, r15: 0,
-            rip: 0, rflags: 0,
-            cr0: 0, cr2: 0, cr3: 0, cr4: 0,
-            cs: 0, ds: 0, es: 0, fs: 0, gs: 0, ss: 0,
-        };
-        
-        // Capture general purpose registers
-        unsafe {
-            core::arch::asm!(
-                "mov {}, rax",
-                "mov {}, rbx",
-                "mov {}, rcx",
-                "mov {}, rdx",
-                "mov {}, rsi",
-                "mov {}, rdi",
-                "mov {}, rbp",
-                "mov {}, rsp",
-                "mov {}, r8",
-                "mov {}, r9",
-                "mov {}, r10",
-                "mov {}, r11",
-                "mov {}, r12",
-                "mov {}, r13",
-                "mov {}, r14",
-                "mov {}, r15",
-                out(reg) context.rax,
-                out(reg) context.rbx,
-                out(reg) context.rcx,
-                out(reg) context.rdx,
-                out(reg) context.rsi,
-                out(reg) context.rdi,
-                out(reg) context.rbp,
-                out(reg) context.rsp,
-                out(reg) context.r8,
-                out(reg) context.r9,
-                out(reg) context.r10,
-                out(reg) context.r11,
-                out(reg) context.r12,
-                out(reg) context.r13,
-                out(reg) context.r14,
-                out(reg) context.r15,
-            );
-            
-            // Get RIP and RFLAGS
-            core::arch::asm!(
-                "lea {}, [rip]",
-                "pushfq",
-                "pop {}",
-                out(reg) context.rip,
-                out(reg) context.rflags,
-            );
-        }
-        
-        // Capture control registers
-        context.cr0 = Cr0::read_raw();
-        context.cr2 = Cr2::read().as_u64();
-        context.cr3 = Cr3::read().0.start_address().as_u64();
-        context.cr4 = Cr4::read_raw();
-        
-        // Capture segment registers
-        unsafe {
-            core::arch::asm!(
-                "mov {}, cs",
-                "mov {}, ds",
-                "mov {}, es",
-                "mov {}, fs",
-                "mov {}, gs",
-                "mov {}, ss",
-                out(reg) context.cs,
-                out(reg) context.ds,
-                out(reg) context.es,
-                out(reg) context.fs,
-                out(reg) context.gs,
-                out(reg) context.ss,
-            );
-        }
-        
-        context
-    }
-    
-    fn create_dump_header(&self, panic_info: &core::panic::PanicInfo) -> CrashDumpHeader {
-        let panic_msg = format!("{}", panic_info);
-        
-        CrashDumpHeader {
-            signature: *b"KDUMPV01",
-            version: 1,
-            header_size: core::mem::size_of::<CrashDumpHeader>() as u32,
-            timestamp: self.get_timestamp(),
-            panic_message_offset: core::mem::size_of::<CrashDumpHeader>() as u64,
-            panic_message_length: panic_msg.len() as u64,
-            cpu_count: 1,  // Would get actual CPU count
-            current_cpu: 0,  // Would get current CPU
-            physical_memory_size: self.get_physical_memory_size(),
-            dump_type: *self.dump_type.lock(),
-            compression: *self.compression.lock(),
-            checksum: 0,  // Would calculate actual checksum
-        }
-    }
-    
-    fn create_mini_dump(&self, header: &CrashDumpHeader, context: &CpuContext, 
-                        panic_info: &core::panic::PanicInfo) -> Result<(), String> {
-        crate::serial_println!("[KDUMP] Creating mini dump...");
-        
-        // Mini dump includes:
-        // - Crash dump header
-        // - CPU context
-        // - Stack trace
-        // - Panic message
-        // - Essential kernel data structures
-        
-        // Save stack area around RSP
-        let stack_size = 8192;  // 8KB of stack
-        let stack_start = (context.rsp - stack_size / 2) & !0xF;  // Align
-        
-        crate::serial_println!("[KDUMP] Saving {} bytes of stack from {:#x}", 
-            stack_size, stack_start);
-        
-        // Would write to reserved memory area
-        
-        Ok(())
-    }
-    
-    fn create_kernel_dump(&self, header: &CrashDumpHeader, context: &CpuContext,
-                         panic_info: &core::panic::PanicInfo) -> Result<(), String> {
-        crate::serial_println!("[KDUMP] Creating kernel dump...");
-        
-        // Kernel dump includes all kernel memory:
-        // - Kernel code segment
-        // - Kernel data segment
-        // - Kernel heap
-        // - Kernel stacks
-        // - Page tables
-        
-        // Identify kernel memory regions
-        let regions = self.identify_kernel_regions();
-        
-        for region in regions {
-            crate::serial_println!("[KDUMP] Dumping {:?} region at {:#x} size {:#x}",
-                region.region_type, region.start_address, region.size);
-            
-            // Would copy memory to dump area
-        }
-        
-        Ok(())
-    }
-    
-    fn create_full_dump(&self, header: &CrashDumpHeader, context: &CpuContext,
-                        panic_info: &core::panic::PanicInfo) -> Result<(), String> {
-        crate::serial_println!("[KDUMP] Creating full memory dump...");
-        
-        // Full dump includes all physical memory
-        let mem_size = self.get_physical_memory_size();
-        crate::serial_println!("[KDUMP] Dumping {} MB of physical memory", 
-            mem_size / (1024 * 1024));
-        
-        // Would copy all physical memory
-        
-        Ok(())
+
+    fn collect(&self, dump_id: u64, panic_message: String, config: &DumpConfig) -> DumpImage {
+        let current = capture_cpu_context();
+        let crashing_cpu = current.cpu_id;
+
+        let mut cpus = Vec::new();
+        if config.dump_type != DumpType::LiveDump {
+            let stopped = stop_other_cpus();
+            for (cpu_id, slot) in SAVED_CPUS.iter().enumerate() {
+                if cpu_id as u32 == crashing_cpu {
+                    continue;
+                }
+                if let Some(context) = slot.try_lock().and_then(|mut saved| saved.take()) {
+                    cpus.push(context);
+                }
+            }
+            crate::serial_println!("[KDUMP] Saved registers of {} of {} other CPUs",
+                cpus.len(), stopped);
+        }
+        cpus.insert(0, current);
+
+        let mut memory: Vec<MemoryRegion> = cpus
+            .iter()
+            .map(|cpu| MemoryRegion {
+                start_address: cpu.rsp.saturating_sub(STACK_CAPTURE_SIZE / 2) & !(PAGE_SIZE - 1),
+                size: STACK_CAPTURE_SIZE,
+                region_type: MemoryRegionType::KernelStack,
+                flags: cpu.cpu_id,
+            })
+            .collect();
+        match config.dump_type {
+            DumpType::MiniDump => {}
+            DumpType::KernelDump | DumpType::LiveDump => {
+                memory.extend(config.memory_ranges.iter().map(|&(start, size)| MemoryRegion {
+                    start_address: start,
+                    size,
+                    region_type: MemoryRegionType::KernelData,
+                    flags: 0,
+                }));
+            }
+            DumpType::FullDump => memory.push(MemoryRegion {
+                start_address: 0,
+                size: crate::memory::get_total_memory(),
+                region_type: MemoryRegionType::KernelData,
+                flags: 0,
+            }),
+        }
+
+        DumpImage {
+            dump_id,
+            timestamp: crate::time::get_timestamp(),
+            dump_type: config.dump_type,
+            crashing_cpu,
+            panic_message,
+            cpus,
+            kernel_log: crate::klog::snapshot(),
+            modules: loaded_modules(),
+            memory,
+        }
     }
-    
-    fn create_live_dump(&self, header: &CrashDumpHeader, context: &CpuContext) -> Result<(), String> {
-        crate::serial_println!("[KDUMP] Creating live system dump...");
-        
-        // Live dump is created without stopping the system
-        // Useful for debugging hangs and performance issues
-        
-        Ok(())
+
+    fn write_dump(&self, image: &DumpImage, config: &DumpConfig) -> Result<(), String> {
+        let mut written = false;
+        let mut error = None;
+
+        if let Some((device, partition)) = config.partition {
+            match PartitionSink::open(device, partition).and_then(|mut sink| image.write_to(&mut sink)) {
+                Ok(()) => {
+                    crate::serial_println!("[KDUMP] Dump written to device {} partition {}",
+                        device, partition);
+                    written = true;
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        if let Some(port) = config.serial_port {
+            crate::serial_println!("[KDUMP] Sending {} byte dump over serial port {:#x}",
+                image.total_size(), port);
+            match serial_link::send(image, port) {
+                Ok(()) => written = true,
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) if !written => Err(e),
+            Some(e) => {
+                crate::serial_println!("[KDUMP] {}", e);
+                Ok(())
+            }
+            None if !written => Err("No dump destination configured".into()),
+            None => Ok(()),
+        }
     }
-    
-    fn identify_kernel_regions(&self) -> Vec<MemoryRegion> {
-        let mut regions = Vec::new();
-        
-        // Add kernel code region
-        regions.push(MemoryRegion {
-            start_address: 0x200000,  // Example kernel base
-            size: 0x100000,  // 1MB code
-            region_type: MemoryRegionType::KernelCode,
-            flags: 0,
-        });
-        
-        // Add kernel data region
-        regions.push(MemoryRegion {
-            start_address: 0x300000,
-            size: 0x200000,  // 2MB data
-            region_type: MemoryRegionType::KernelData,
-            flags: 0,
-        });
-        
-        // Would add actual regions from memory map
-        
-        regions
-    }
-    
-    fn write_dump_to_storage(&self, dump_id: u64) -> Result<(), String> {
-        // Write dump to persistent storage
-        // Options:
-        // 1. Write to reserved disk partition
-        // 2. Send over network (netdump)
-        // 3. Write to USB storage
-        // 4. Store in NVRAM
-        
-        crate::serial_println!("[KDUMP] Writing dump {} to storage", dump_id);
-        
-        // For now, just indicate where it would be written
-        if let Some(ref area) = self.reserved_memory {
-            crate::serial_println!("[KDUMP] Dump saved at physical address {:#x}", 
-                area.physical_address);
-        }
-        
-        Ok(())
+
+    /// Send the last dump over serial again, resuming where the receiver
+    /// left off
+    pub fn resend_last_dump(&self) -> Result<(), String> {
+        let port = self.config.lock().serial_port.ok_or("No dump serial port configured")?;
+        let last = self.last_dump.lock();
+        let image = last.as_ref().ok_or("No crash dump has been taken")?;
+        serial_link::send(image, port)
     }
-    
-    fn get_timestamp(&self) -> u64 {
-        // Would get actual timestamp
-        0
-    }
-    
-    fn get_physical_memory_size(&self) -> u64 {
-        // Would get actual physical memory size
-        256 * 1024 * 1024  // 256MB for example
-    }
-    
-    pub fn analyze_dump(&self, dump_addr: u64) -> Result<DumpAnalysis, String> {
-        crate::serial_println!("[KDUMP] Analyzing dump at {:#x}", dump_addr);
-        
-        // Read and validate dump header
-        let header = unsafe {
-            &*(dump_addr as *const CrashDumpHeader)
-        };
-        
-        if &header.signature != b"KDUMPV01" {
-            return Err("Invalid dump signature".into());
-        }
-        
-        // Perform analysis
-        let analysis = DumpAnalysis {
-            dump_valid: true,
-            panic_reason: String::new(),  // Would extract from dump
-            faulting_address: 0,
-            faulting_instruction: 0,
-            stack_trace: Vec::new(),
+
+    pub fn analyze_dump(&self, image: &DumpImage) -> DumpAnalysis {
+        let crashing = image.cpus.first();
+        let mut stack_trace = Vec::new();
+        if let Some(cpu) = crashing {
+            // The crashed stacks are still in memory when analysing the
+            // dump that was just taken
+            let start = super::unwind::Frame::new(cpu.rip, cpu.rsp, cpu.rbp);
+            stack_trace = super::unwind::format_frames(&super::unwind::backtrace(start, super::unwind::MAX_FRAMES));
+        }
+
+        DumpAnalysis {
+            dump_valid: crashing.is_some(),
+            panic_reason: image.panic_message.clone(),
+            faulting_address: crashing.map_or(0, |cpu| cpu.cr2),
+            faulting_instruction: crashing.map_or(0, |cpu| cpu.rip),
+            stack_trace,
             suggested_cause: String::from("Unknown"),
-        };
-        
-        Ok(analysis)
+        }
     }
-    
+
     pub fn configure_kexec_kernel(&self, kernel_path: &str) -> Result<(), String> {
         // Configure kexec to load crash kernel
         crate::serial_println!("[KDUMP] Configuring kexec crash kernel: {}", kernel_path);
-        
+
         // Would:
         // 1. Load crash kernel into reserved memory
         // 2. Set up boot parameters for crash kernel
         // 3. Register crash handler to trigger kexec
-        
+
         Ok(())
     }
 }
 
+fn capture_cpu_context() -> CpuContext {
+    let mut context = CpuContext {
+        cpu_id: crate::smp::percpu::get_cpu_id(),
+        apic_id: crate::smp::percpu::get_apic_id() as u32,
+        ..CpuContext::default()
+    };
+
+    // General purpose registers, in CpuContext order from rax
+    let mut registers = [0u64; 16];
+    unsafe {
+        core::arch::asm!(
+            "mov [{0} + 0x00], rax",
+            "mov [{0} + 0x08], rbx",
+            "mov [{0} + 0x10], rcx",
+            "mov [{0} + 0x18], rdx",
+            "mov [{0} + 0x20], rsi",
+            "mov [{0} + 0x28], rdi",
+            "mov [{0} + 0x30], rbp",
+            "mov [{0} + 0x38], rsp",
+            "mov [{0} + 0x40], r8",
+            "mov [{0} + 0x48], r9",
+            "mov [{0} + 0x50], r10",
+            "mov [{0} + 0x58], r11",
+            "mov [{0} + 0x60], r12",
+            "mov [{0} + 0x68], r13",
+            "mov [{0} + 0x70], r14",
+            "mov [{0} + 0x78], r15",
+            in(reg) registers.as_mut_ptr(),
+            options(nostack, preserves_flags),
+        );
+
+        // Get RIP and RFLAGS
+        core::arch::asm!(
+            "lea {}, [rip]",
+            "pushfq",
+            "pop {}",
+            out(reg) context.rip,
+            out(reg) context.rflags,
+        );
+
+        core::arch::asm!(
+            "mov {0:x}, cs",
+            "mov {1:x}, ds",
+            "mov {2:x}, es",
+            "mov {3:x}, fs",
+            "mov {4:x}, gs",
+            "mov {5:x}, ss",
+            out(reg) context.cs,
+            out(reg) context.ds,
+            out(reg) context.es,
+            out(reg) context.fs,
+            out(reg) context.gs,
+            out(reg) context.ss,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    [
+        context.rax, context.rbx, context.rcx, context.rdx,
+        context.rsi, context.rdi, context.rbp, context.rsp,
+        context.r8, context.r9, context.r10, context.r11,
+        context.r12, context.r13, context.r14, context.r15,
+    ] = registers;
+
+    // Capture control registers
+    context.cr0 = Cr0::read_raw();
+    context.cr2 = Cr2::read().as_u64();
+    context.cr3 = Cr3::read().0.start_address().as_u64();
+    context.cr4 = Cr4::read_raw();
+
+    context
+}
+
+// Ask every other online CPU to save its registers and halt; returns how
+// many were asked
+fn stop_other_cpus() -> u32 {
+    // The panicking CPU may hold the SMP lock itself
+    let others = match crate::smp::SMP_MANAGER.try_lock() {
+        Some(smp) => smp.online_cpu_count().saturating_sub(1),
+        None => return 0,
+    };
+    if others == 0 {
+        return 0;
+    }
+
+    CPUS_SAVED.store(0, Ordering::SeqCst);
+    crate::smp::ipi::send_panic_ipi();
+    for _ in 0..CPU_SAVE_TIMEOUT_SPINS {
+        if CPUS_SAVED.load(Ordering::SeqCst) >= others {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    others
+}
+
+// The kernel image and kernel modules, then the current process's DLLs
+fn loaded_modules() -> Vec<ModuleRecord> {
+    let mut modules = Vec::new();
+    if let Some(extents) = super::symbols::SYMBOLS.try_module_extents() {
+        for (name, (start, end)) in extents {
+            modules.push(ModuleRecord { name, base: start, size: end - start });
+        }
+    }
+    // Modules of the process that was running
+    let process = crate::process::PROCESS_MANAGER.try_lock().and_then(|pm| pm.current_process);
+    let loader = crate::win32::loader::MODULE_LOADER.try_lock();
+    if let (Some(process), Some(loader)) = (process, loader) {
+        if let Some(process_modules) = loader.process(process) {
+            for module in process_modules.modules() {
+                modules.push(ModuleRecord {
+                    name: module.name.clone(),
+                    base: module.base,
+                    size: module.size as u64,
+                });
+            }
+        }
+    }
+    modules
+}
+
+/// Save this CPU's registers for a dump in progress. Called by CPUs that
+/// receive the panic IPI, before they halt.
+pub fn save_cpu_state() {
+    let context = capture_cpu_context();
+    if let Some(slot) = SAVED_CPUS.get(context.cpu_id as usize) {
+        if let Some(mut saved) = slot.try_lock() {
+            *saved = Some(context);
+        }
+    }
+    CPUS_SAVED.fetch_add(1, Ordering::SeqCst);
+}
+
 #[derive(Debug)]
 pub struct DumpAnalysis {
     pub dump_valid: bool,
@@ -461,10 +781,212 @@ pub struct DumpAnalysis {
     pub suggested_cause: String,
 }
 
+// Resumable dump transfer over a polled UART
+//
+// The kernel sends frames
+//
+//   "KDF" | type u8 | sequence u32 | offset u64 | length u16 | payload | crc32
+//
+// with the CRC covering type through payload. HELLO carries the dump id and
+// total size; the receiver answers 'R' and the little-endian offset it
+// already holds for that dump (0 for a new one), and the transfer continues
+// from there. DATA frames carry the stream at `offset`; END carries the
+// total size. Both are answered with ACK or NAK. If the link goes quiet the
+// kernel says HELLO again, so a receiver that restarts picks up where it
+// stopped. When nobody answers the first HELLO the dump is streamed without
+// acknowledgements, for capture to a file.
+pub mod serial_link {
+    use super::{crc32, DumpImage, DumpSink};
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use x86_64::instructions::port::Port;
+
+    pub const COM1: u16 = 0x3f8;
+    pub const COM2: u16 = 0x2f8;
+
+    pub const FRAME_MAGIC: &[u8; 3] = b"KDF";
+    pub const FRAME_HELLO: u8 = 1;
+    pub const FRAME_DATA: u8 = 2;
+    pub const FRAME_END: u8 = 3;
+    pub const ACK: u8 = 0x06;
+    pub const NAK: u8 = 0x15;
+    pub const RESUME: u8 = b'R';
+    pub const MAX_PAYLOAD: usize = 1024;
+
+    // Roughly a second of polling the line status register
+    const RESPONSE_TIMEOUT_SPINS: u32 = 1_000_000;
+    const MAX_RETRIES: u32 = 8;
+    const MAX_HANDSHAKES: u32 = 4;
+    const LINK_LOST: &str = "Dump receiver stopped responding";
+
+    struct Uart {
+        base: u16,
+    }
+
+    impl Uart {
+        // 115200 baud, 8N1, FIFOs on, polled
+        fn open(base: u16) -> Self {
+            unsafe {
+                Port::<u8>::new(base + 1).write(0x00);
+                Port::<u8>::new(base + 3).write(0x80);
+                Port::<u8>::new(base).write(0x01);
+                Port::<u8>::new(base + 1).write(0x00);
+                Port::<u8>::new(base + 3).write(0x03);
+                Port::<u8>::new(base + 2).write(0xc7);
+                Port::<u8>::new(base + 4).write(0x03);
+            }
+            Self { base }
+        }
+
+        fn line_status(&self) -> u8 {
+            unsafe { Port::<u8>::new(self.base + 5).read() }
+        }
+
+        fn write(&self, bytes: &[u8]) {
+            for &byte in bytes {
+                while self.line_status() & 0x20 == 0 {
+                    core::hint::spin_loop();
+                }
+                unsafe { Port::<u8>::new(self.base).write(byte) };
+            }
+        }
+
+        fn read_timeout(&self) -> Option<u8> {
+            for _ in 0..RESPONSE_TIMEOUT_SPINS {
+                if self.line_status() & 0x01 != 0 {
+                    return Some(unsafe { Port::<u8>::new(self.base).read() });
+                }
+                core::hint::spin_loop();
+            }
+            None
+        }
+
+        // Drop stale replies before waiting for a new one
+        fn drain(&self) {
+            while self.line_status() & 0x01 != 0 {
+                unsafe { Port::<u8>::new(self.base).read() };
+            }
+        }
+    }
+
+    struct SerialSink {
+        uart: Uart,
+        sequence: u32,
+        resume_from: u64,
+        acknowledged: bool,
+        total_size: u64,
+    }
+
+    impl SerialSink {
+        fn send_frame(&mut self, kind: u8, offset: u64, payload: &[u8]) {
+            let mut frame = Vec::with_capacity(payload.len() + 22);
+            frame.extend_from_slice(FRAME_MAGIC);
+            frame.push(kind);
+            frame.extend_from_slice(&self.sequence.to_le_bytes());
+            frame.extend_from_slice(&offset.to_le_bytes());
+            frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            frame.extend_from_slice(payload);
+            let crc = crc32(&frame[3..]);
+            frame.extend_from_slice(&crc.to_le_bytes());
+            self.uart.drain();
+            self.uart.write(&frame);
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+
+        // Send a frame until it is acknowledged
+        fn exchange(&mut self, kind: u8, offset: u64, payload: &[u8]) -> Result<(), String> {
+            if !self.acknowledged {
+                self.send_frame(kind, offset, payload);
+                return Ok(());
+            }
+            for _ in 0..MAX_RETRIES {
+                self.send_frame(kind, offset, payload);
+                if self.uart.read_timeout() == Some(ACK) {
+                    return Ok(());
+                }
+            }
+            Err(LINK_LOST.into())
+        }
+
+        // HELLO until the receiver says where to resume
+        fn handshake(&mut self, dump_id: u64) -> Option<u64> {
+            let mut hello = [0u8; 16];
+            hello[0..8].copy_from_slice(&dump_id.to_le_bytes());
+            hello[8..16].copy_from_slice(&self.total_size.to_le_bytes());
+            for _ in 0..MAX_RETRIES {
+                self.send_frame(FRAME_HELLO, 0, &hello);
+                if self.uart.read_timeout() != Some(RESUME) {
+                    continue;
+                }
+                let mut offset = [0u8; 8];
+                if offset.iter_mut().all(|b| self.uart.read_timeout().map(|v| *b = v).is_some()) {
+                    return Some(u64::from_le_bytes(offset).min(self.total_size));
+                }
+            }
+            None
+        }
+    }
+
+    impl DumpSink for SerialSink {
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), String> {
+            // Skip what the receiver already has
+            let end = offset + data.len() as u64;
+            if end <= self.resume_from {
+                return Ok(());
+            }
+            let skip = self.resume_from.saturating_sub(offset) as usize;
+            let mut position = offset + skip as u64;
+            for chunk in data[skip..].chunks(MAX_PAYLOAD) {
+                self.exchange(FRAME_DATA, position, chunk)?;
+                position += chunk.len() as u64;
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), String> {
+            let total = self.total_size.to_le_bytes();
+            self.exchange(FRAME_END, self.total_size, &total)
+        }
+    }
+
+    /// Send a dump, reconnecting and resuming if the receiver goes away
+    pub fn send(image: &DumpImage, port: u16) -> Result<(), String> {
+        let mut sink = SerialSink {
+            uart: Uart::open(port),
+            sequence: 0,
+            resume_from: 0,
+            acknowledged: true,
+            total_size: image.total_size(),
+        };
+
+        for attempt in 0..MAX_HANDSHAKES {
+            match sink.handshake(image.dump_id) {
+                Some(offset) => {
+                    sink.acknowledged = true;
+                    sink.resume_from = offset;
+                }
+                None if attempt == 0 => {
+                    crate::serial_println!("[KDUMP] No dump receiver on {:#x}, streaming unacknowledged", port);
+                    sink.acknowledged = false;
+                    sink.resume_from = 0;
+                }
+                None => break,
+            }
+            match image.write_to(&mut sink) {
+                Err(e) if e == LINK_LOST => {
+                    crate::serial_println!("[KDUMP] {}, reconnecting", LINK_LOST);
+                }
+                result => return result,
+            }
+        }
+        Err(LINK_LOST.into())
+    }
+}
+
 // ELF core dump format support
 pub mod elf_core {
     use super::*;
-    
+
     #[repr(C)]
     pub struct ElfHeader {
         pub magic: [u8; 4],  // 0x7f, 'E', 'L', 'F'
@@ -488,7 +1010,7 @@ pub mod elf_core {
         pub shnum: u16,
         pub shstrndx: u16,
     }
-    
+
     #[repr(C)]
     pub struct ProgramHeader {
         pub p_type: u32,     // PT_NOTE, PT_LOAD
@@ -500,25 +1022,39 @@ pub mod elf_core {
         pub p_memsz: u64,
         pub p_align: u64,
     }
-    
+
     pub fn create_elf_core_dump(context: &CpuContext) -> Vec<u8> {
         // Create ELF core dump format compatible with GDB
         let mut dump = Vec::new();
-        
+
         // Would build proper ELF core file
-        
+
         dump
     }
 }
 
 // Public API
 pub fn init() {
-    // Reserve memory for crash dumps
-    CRASH_DUMP.reserve_dump_memory(64 * 1024 * 1024)  // 64MB
-        .unwrap_or_else(|e| {
-            crate::serial_println!("[KDUMP] Failed to reserve memory: {}", e);
-        });
-    
+    // Touch the per-CPU save slots now so the panic path does not allocate them
+    lazy_static::initialize(&SAVED_CPUS);
+
+    // Use the first partition reserved for crash dumps, if there is one
+    let devices = crate::drivers::storage::get_storage_device_count() as u32;
+    let partition = (0..devices).find_map(|device| {
+        crate::drivers::storage::get_storage_partitions(device)
+            .into_iter()
+            .find(|p| p.partition_type == DUMP_PARTITION_TYPE)
+            .map(|p| (device, p.partition_number))
+    });
+    if let Some((device, number)) = partition {
+        CRASH_DUMP.config.lock().partition = Some((device, number));
+        crate::serial_println!("[KDUMP] Using device {} partition {} for crash dumps", device, number);
+    }
+
+    // Kernel dumps include the kernel heap unless configured otherwise
+    let heap = crate::allocator::heap_range();
+    CRASH_DUMP.config.lock().memory_ranges.push(heap);
+
     crate::serial_println!("[KDUMP] Crash dump system initialized");
 }
 
@@ -531,20 +1067,43 @@ pub fn create_dump(panic_info: &core::panic::PanicInfo) {
 
 pub fn trigger_test_dump() {
     crate::serial_println!("[KDUMP] Triggering test crash dump...");
-    let test_panic = core::panic::PanicInfo::internal_constructor(
-        Some(&"Test crash dump"),
-        core::panic::Location::caller(),
-        false,
-    );
-    create_dump(&test_panic);
+    CRASH_DUMP.create_dump_with_message("Test crash dump".to_string())
+        .unwrap_or_else(|e| {
+            crate::serial_println!("[KDUMP] Failed to create dump: {}", e);
+        });
+}
+
+pub fn set_enabled(enabled: bool) {
+    CRASH_DUMP.enabled.store(enabled, Ordering::SeqCst);
 }
 
 pub fn set_dump_type(dump_type: DumpType) {
-    *CRASH_DUMP.dump_type.lock() = dump_type;
+    CRASH_DUMP.config.lock().dump_type = dump_type;
     crate::serial_println!("[KDUMP] Dump type set to {:?}", dump_type);
 }
 
+pub fn set_partition(partition: Option<(u32, u32)>) {
+    CRASH_DUMP.config.lock().partition = partition;
+}
+
+pub fn set_serial_port(port: Option<u16>) {
+    CRASH_DUMP.config.lock().serial_port = port;
+}
+
+pub fn add_memory_range(start: u64, size: u64) {
+    CRASH_DUMP.config.lock().memory_ranges.push((start, size));
+}
+
+pub fn clear_memory_ranges() {
+    CRASH_DUMP.config.lock().memory_ranges.clear();
+}
+
+pub fn resend_last_dump() -> Result<(), String> {
+    CRASH_DUMP.resend_last_dump()
+}
+
 pub fn analyze_last_dump() -> Result<DumpAnalysis, String> {
-    // Would find and analyze the last dump
-    CRASH_DUMP.analyze_dump(0)
-}
\ No newline at end of file
+    let last = CRASH_DUMP.last_dump.lock();
+    let image = last.as_ref().ok_or_else(|| "No crash dump has been taken".to_string())?;
+    Ok(CRASH_DUMP.analyze_dump(image))
+}
@@ -58,9 +58,6 @@ impl GdbStub {
     
     pub fn init(&self) {
         crate::serial_println!("[KGDB] GDB stub initialized, waiting for connection...");
-        
-        // Enable serial interrupt for GDB communication
-        crate::serial::enable_interrupt();
     }
     
     pub fn handle_connection(&self) {
@@ -175,8 +172,7 @@ impl GdbStub {
     }
     
     fn send_byte(&self, byte: u8) {
-        use core::fmt::Write;
-        crate::serial::SERIAL1.lock().write_char(byte as char).ok();
+        crate::serial::write_byte(byte);
     }
     
     fn calculate_checksum(&self, data: &[u8]) -> u8 {
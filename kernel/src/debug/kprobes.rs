@@ -0,0 +1,339 @@
+// Dynamic Probes
+// A probe replaces the first byte of a kernel function with int3. Kernel
+// functions open with a one-byte push (push rbp; the kernel is built with
+// frame pointers), so the breakpoint handler records a trace event with the
+// function's register arguments and resumes in a per-probe trampoline that
+// executes the displaced push and jumps back. The original byte is not put
+// back while the probe is armed, so a probe fires on every CPU without
+// single-stepping.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::VirtAddr;
+
+use super::trace::{self, TraceEvent};
+use crate::smp::MAX_CPUS;
+
+pub const MAX_PROBES: usize = 64;
+const TRAMPOLINE_SIZE: usize = 16;
+const INT3: u8 = 0xcc;
+const JMP_REL32: u8 = 0xe9;
+
+const PROBE_FIELDS: &[&str] = &["ip", "arg1", "arg2", "arg3", "arg4", "arg5", "arg6"];
+
+// Registers saved by the breakpoint entry stub, lowest address first
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Breakpoint entry: save the general purpose and SSE registers (the handler
+// is ordinary Rust code and may use both), then call int3_handler with the
+// trap frame. On entry rsp is 8 modulo 16, so after 15 pushes the XMM save
+// area and the call are aligned.
+core::arch::global_asm!(
+    ".pushsection .text.kprobes, \"ax\"",
+    ".global kprobe_int3_entry",
+    "kprobe_int3_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "sub rsp, 256",
+    "movdqa [rsp + 0x00], xmm0",
+    "movdqa [rsp + 0x10], xmm1",
+    "movdqa [rsp + 0x20], xmm2",
+    "movdqa [rsp + 0x30], xmm3",
+    "movdqa [rsp + 0x40], xmm4",
+    "movdqa [rsp + 0x50], xmm5",
+    "movdqa [rsp + 0x60], xmm6",
+    "movdqa [rsp + 0x70], xmm7",
+    "movdqa [rsp + 0x80], xmm8",
+    "movdqa [rsp + 0x90], xmm9",
+    "movdqa [rsp + 0xa0], xmm10",
+    "movdqa [rsp + 0xb0], xmm11",
+    "movdqa [rsp + 0xc0], xmm12",
+    "movdqa [rsp + 0xd0], xmm13",
+    "movdqa [rsp + 0xe0], xmm14",
+    "movdqa [rsp + 0xf0], xmm15",
+    "lea rdi, [rsp + 256]",
+    "cld",
+    "call {handler}",
+    "movdqa xmm0, [rsp + 0x00]",
+    "movdqa xmm1, [rsp + 0x10]",
+    "movdqa xmm2, [rsp + 0x20]",
+    "movdqa xmm3, [rsp + 0x30]",
+    "movdqa xmm4, [rsp + 0x40]",
+    "movdqa xmm5, [rsp + 0x50]",
+    "movdqa xmm6, [rsp + 0x60]",
+    "movdqa xmm7, [rsp + 0x70]",
+    "movdqa xmm8, [rsp + 0x80]",
+    "movdqa xmm9, [rsp + 0x90]",
+    "movdqa xmm10, [rsp + 0xa0]",
+    "movdqa xmm11, [rsp + 0xb0]",
+    "movdqa xmm12, [rsp + 0xc0]",
+    "movdqa xmm13, [rsp + 0xd0]",
+    "movdqa xmm14, [rsp + 0xe0]",
+    "movdqa xmm15, [rsp + 0xf0]",
+    "add rsp, 256",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    // One trampoline per probe slot: the displaced instruction, then a jump
+    // back to the instruction after it
+    ".balign 16",
+    ".global kprobe_trampolines",
+    "kprobe_trampolines:",
+    ".fill {size}, 1, 0xcc",
+    ".popsection",
+    handler = sym int3_handler,
+    size = const MAX_PROBES * TRAMPOLINE_SIZE,
+);
+
+extern "C" {
+    fn kprobe_int3_entry();
+    static kprobe_trampolines: [u8; MAX_PROBES * TRAMPOLINE_SIZE];
+}
+
+/// Address of the breakpoint entry stub, for the IDT
+pub fn int3_entry() -> VirtAddr {
+    VirtAddr::new(kprobe_int3_entry as *const () as u64)
+}
+
+fn trampoline(slot: usize) -> u64 {
+    core::ptr::addr_of!(kprobe_trampolines) as u64 + (slot * TRAMPOLINE_SIZE) as u64
+}
+
+// Armed probes as seen by the breakpoint handler, which must not take locks
+struct Slot {
+    address: AtomicU64,
+    event: AtomicPtr<TraceEvent>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            address: AtomicU64::new(0),
+            event: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+}
+
+static SLOTS: [Slot; MAX_PROBES] = [const { Slot::new() }; MAX_PROBES];
+
+// Set while a CPU records a probe hit, so a probe on a function the tracer
+// itself calls passes through instead of recursing
+static RECORDING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+pub struct Probe {
+    pub name: String,
+    pub address: u64,
+    pub symbol: String,
+    slot: usize,
+    original: u8,
+    event: &'static TraceEvent,
+}
+
+impl Probe {
+    pub fn hits(&self) -> u64 {
+        self.event.hits()
+    }
+}
+
+// Serializes arming and disarming
+static PROBES: Mutex<Vec<Probe>> = Mutex::new(Vec::new());
+
+extern "C" fn int3_handler(frame: &mut TrapFrame) {
+    let address = frame.rip.wrapping_sub(1);
+
+    for (index, slot) in SLOTS.iter().enumerate() {
+        if slot.address.load(Ordering::Acquire) != address {
+            continue;
+        }
+        let recording = &RECORDING[crate::smp::current_cpu_id() as usize % MAX_CPUS];
+        if !recording.swap(true, Ordering::Acquire) {
+            if let Some(event) = unsafe { slot.event.load(Ordering::Acquire).as_ref() } {
+                event.emit(&[address, frame.rdi, frame.rsi, frame.rdx, frame.rcx, frame.r8, frame.r9]);
+            }
+            recording.store(false, Ordering::Release);
+        }
+        frame.rip = trampoline(index);
+        return;
+    }
+
+    // A probe disarmed while this CPU was trapping on it: rerun the
+    // restored instruction
+    if frame.cs & 3 == 0 && unsafe { core::ptr::read_volatile(address as *const u8) } != INT3 {
+        frame.rip = address;
+        return;
+    }
+
+    crate::println!("EXCEPTION: BREAKPOINT");
+    crate::println!("RIP: {:#x} RSP: {:#x} RFLAGS: {:#x} CS: {:#x}", frame.rip, frame.rsp, frame.rflags, frame.cs);
+}
+
+// Write to kernel text, which is mapped read-only. CR0.WP cannot be cleared
+// while CR4.CET is set, so CET is off for the write as well.
+fn write_text(address: u64, bytes: &[u8]) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let cr4 = Cr4::read();
+        let cr0 = Cr0::read();
+        Cr4::write(cr4 - Cr4Flags::CONTROL_FLOW_ENFORCEMENT);
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        for (i, &byte) in bytes.iter().enumerate() {
+            core::ptr::write_volatile((address + i as u64) as *mut u8, byte);
+        }
+        Cr0::write(cr0);
+        Cr4::write(cr4);
+    });
+}
+
+fn parse_address(target: &str) -> Option<u64> {
+    let hex = target.strip_prefix("0x")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Arm a probe on a function (by name) or an address; returns the probe name
+pub fn add_probe(target: &str, name: Option<&str>) -> Result<String, &'static str> {
+    let address = match parse_address(target) {
+        Some(address) => address,
+        None => super::symbols::SYMBOLS.find_symbol_by_name(target).ok_or("unknown function")?.address,
+    };
+    let name = name.map_or_else(|| target.rsplit("::").next().unwrap_or(target).to_string(), |n| n.to_string());
+
+    let mut probes = PROBES.lock();
+    if probes.iter().any(|probe| probe.name == name) {
+        return Err("a probe with that name exists");
+    }
+    if probes.iter().any(|probe| probe.address == address) {
+        return Err("address already probed");
+    }
+    if !super::is_mapped(address) {
+        return Err("address not mapped");
+    }
+
+    // Only single-byte pushes can be displaced into a trampoline as-is
+    let original = unsafe { core::ptr::read_volatile(address as *const u8) };
+    if !(0x50..=0x57).contains(&original) {
+        return Err("instruction at address is not a one-byte push");
+    }
+
+    let slot = (0..MAX_PROBES)
+        .find(|&slot| !probes.iter().any(|probe| probe.slot == slot))
+        .ok_or("too many probes")?;
+    let rel32 = (address + 1).wrapping_sub(trampoline(slot) + 6) as i64;
+    if rel32 < i32::MIN as i64 || rel32 > i32::MAX as i64 {
+        return Err("address out of range of the trampolines");
+    }
+
+    // Events stay registered after the probe is removed so records already
+    // in the buffers still decode
+    let event: &'static TraceEvent = Box::leak(Box::new(TraceEvent::new(
+        "kprobes",
+        Box::leak(name.clone().into_boxed_str()),
+        PROBE_FIELDS,
+    )));
+    trace::register_event(event);
+    event.set_enabled(true);
+
+    let mut code = [INT3; TRAMPOLINE_SIZE];
+    code[0] = original;
+    code[1] = JMP_REL32;
+    code[2..6].copy_from_slice(&(rel32 as i32).to_le_bytes());
+    write_text(trampoline(slot), &code);
+
+    SLOTS[slot].event.store(event as *const TraceEvent as *mut TraceEvent, Ordering::Release);
+    SLOTS[slot].address.store(address, Ordering::Release);
+    write_text(address, &[INT3]);
+
+    let symbol = super::symbols::format_address(address);
+    crate::serial_println!("[KPROBES] Armed {} at {:#x} ({})", name, address, symbol);
+    probes.push(Probe {
+        name: name.clone(),
+        address,
+        symbol,
+        slot,
+        original,
+        event,
+    });
+    Ok(name)
+}
+
+pub fn remove_probe(name: &str) -> Result<(), &'static str> {
+    let mut probes = PROBES.lock();
+    let index = probes.iter().position(|probe| probe.name == name).ok_or("no such probe")?;
+    let probe = probes.remove(index);
+
+    // Restore the code before retiring the slot; a CPU already trapping on
+    // the int3 then finds no slot and reruns the restored instruction
+    write_text(probe.address, &[probe.original]);
+    SLOTS[probe.slot].address.store(0, Ordering::Release);
+    probe.event.set_enabled(false);
+
+    crate::serial_println!("[KPROBES] Removed {}", name);
+    Ok(())
+}
+
+pub fn remove_all() {
+    let names: Vec<String> = PROBES.lock().iter().map(|probe| probe.name.clone()).collect();
+    for name in names {
+        let _ = remove_probe(&name);
+    }
+}
+
+/// (name, address, symbol, hits) of every armed probe
+pub fn list() -> Vec<(String, u64, String, u64)> {
+    PROBES
+        .lock()
+        .iter()
+        .map(|probe| (probe.name.clone(), probe.address, probe.symbol.clone(), probe.hits()))
+        .collect()
+}
@@ -6,10 +6,12 @@ pub mod kasan;      // Kernel Address Sanitizer
 pub mod kgdb;       // GDB remote protocol support
 pub mod profiler;   // System profiling tools
 pub mod trace;      // Tracing infrastructure
+pub mod kprobes;    // Dynamic probes for the tracer
 pub mod watchdog;   // Watchdog and hang detection
 pub mod sysrq;      // Magic SysRq support
 pub mod memleak;    // Memory leak detection
 pub mod symbols;    // Symbol resolution
+pub mod unwind;     // Stack unwinding
 
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -71,6 +73,9 @@ pub fn init() {
     // Initialize symbol resolution
     symbols::init();
     
+    // Register the subsystems' trace events
+    trace::init();
+    
     // Register SysRq handlers
     sysrq::init();
     
@@ -85,6 +90,9 @@ pub fn enhanced_panic_handler(info: &core::panic::PanicInfo) {
     // Disable interrupts
     x86_64::instructions::interrupts::disable();
     
+    // Print synchronously from here on, whatever was interrupted
+    crate::klog::panic_mode();
+    
     // Print panic header
     crate::serial_println!("\n\n=== KERNEL PANIC #{} ===", panic_count + 1);
     crate::serial_println!("{}", info);
@@ -115,44 +123,35 @@ pub fn enhanced_panic_handler(info: &core::panic::PanicInfo) {
 
 // Generate stack trace with symbol resolution
 pub fn generate_stack_trace() -> Option<Vec<String>> {
-    let mut trace = Vec::new();
-    let mut rbp: u64;
-    
-    unsafe {
-        core::arch::asm!("mov {}, rbp", out(reg) rbp);
-    }
-    
-    // Walk the stack
-    for _ in 0..32 {  // Limit depth to prevent infinite loops
-        if rbp == 0 || rbp < 0x1000 {
-            break;
-        }
-        
-        unsafe {
-            let return_addr = *(rbp as *const u64).offset(1);
-            if return_addr == 0 {
-                break;
-            }
-            
-            // Resolve symbol if possible
-            if let Some(symbol) = symbols::resolve_address(return_addr) {
-                trace.push(format!("  [{:#016x}] {}", return_addr, symbol));
-            } else {
-                trace.push(format!("  [{:#016x}] <unknown>", return_addr));
-            }
-            
-            // Move to next frame
-            rbp = *(rbp as *const u64);
-        }
-    }
-    
-    if trace.is_empty() {
+    let frames = unwind::current_frames(unwind::MAX_FRAMES);
+    if frames.is_empty() {
         None
     } else {
-        Some(trace)
+        Some(unwind::format_frames(&frames))
     }
 }
 
+/// Report an unrecoverable CPU exception: the faulting instruction, its
+/// symbol, and a backtrace of the interrupted code
+pub fn oops(title: &str, frame: &x86_64::structures::idt::InterruptStackFrame, frame_pointer: u64) {
+    let rip = frame.instruction_pointer.as_u64();
+    let rsp = frame.stack_pointer.as_u64();
+    crate::serial_println!("\n=== OOPS: {} ===", title);
+    crate::serial_println!("RIP: {:#018x} {}", rip, symbols::format_address(rip));
+    crate::serial_println!("RSP: {:#018x} RBP: {:#018x} RFLAGS: {:#x}", rsp, frame_pointer, frame.cpu_flags);
+    crate::serial_println!("CS: {:#x} SS: {:#x}", frame.code_segment, frame.stack_segment);
+    crate::serial_println!("Call Trace:");
+    unwind::print_backtrace(&unwind::backtrace(unwind::Frame::new(rip, rsp, frame_pointer), unwind::MAX_FRAMES));
+}
+
+/// Whether a virtual address is mapped in the active page tables, so that
+/// debugging code can read it without faulting
+pub fn is_mapped(address: u64) -> bool {
+    // Non-canonical addresses fault before any table is walked
+    x86_64::VirtAddr::try_new(address)
+        .map_or(false, |addr| crate::memory::paging::translate_current(addr).is_some())
+}
+
 // Debug output helpers
 #[macro_export]
 macro_rules! debug_print {
@@ -39,51 +39,10 @@ lazy_static! {
 
 impl SymbolTable {
     pub fn new() -> Self {
-        let mut table = Self {
+        Self {
             symbols: Mutex::new(BTreeMap::new()),
             sorted_addresses: Mutex::new(Vec::new()),
-        };
-        
-        // Register built-in kernel symbols
-        table.register_builtin_symbols();
-        table
-    }
-    
-    fn register_builtin_symbols(&mut self) {
-        // Register known kernel symbols
-        // In a real implementation, these would be loaded from the kernel binary
-        
-        self.add_symbol(Symbol {
-            address: 0x200000,
-            size: 0x1000,
-            name: "_start".to_string(),
-            module: "kernel".to_string(),
-            source_file: Some("src/main.rs".to_string()),
-            line_number: Some(1),
-            symbol_type: SymbolType::Function,
-        });
-        
-        self.add_symbol(Symbol {
-            address: 0x201000,
-            size: 0x100,
-            name: "kernel_main".to_string(),
-            module: "kernel".to_string(),
-            source_file: Some("src/main.rs".to_string()),
-            line_number: Some(50),
-            symbol_type: SymbolType::Function,
-        });
-        
-        self.add_symbol(Symbol {
-            address: 0x202000,
-            size: 0x200,
-            name: "panic_handler".to_string(),
-            module: "kernel".to_string(),
-            source_file: Some("src/main.rs".to_string()),
-            line_number: Some(100),
-            symbol_type: SymbolType::Function,
-        });
-        
-        // Add more symbols as needed
+        }
     }
     
     pub fn add_symbol(&self, symbol: Symbol) {
@@ -99,8 +58,14 @@ impl SymbolTable {
     }
     
     pub fn resolve(&self, address: u64) -> Option<ResolvedSymbol> {
-        let symbols = self.symbols.lock();
-        let addresses = self.sorted_addresses.lock();
+        if let Some(resolved) = kallsyms::lookup(address) {
+            return Some(resolved);
+        }
+        
+        // Symbols registered at runtime; skipped rather than waited for when
+        // a crashing CPU left the table locked
+        let symbols = self.symbols.try_lock()?;
+        let addresses = self.sorted_addresses.try_lock()?;
         
         // Find the symbol containing this address
         let pos = match addresses.binary_search(&address) {
@@ -208,6 +173,9 @@ impl SymbolTable {
     }
     
     pub fn find_symbol_by_name(&self, name: &str) -> Option<Symbol> {
+        if let Some(symbol) = kallsyms::find(name) {
+            return Some(symbol);
+        }
         self.symbols.lock()
             .values()
             .find(|s| s.name == name)
@@ -223,6 +191,23 @@ impl SymbolTable {
             None
         }
     }
+
+    /// Address range covered by each module's symbols, as (start, end).
+    /// Returns None instead of blocking if the table is locked.
+    pub fn try_module_extents(&self) -> Option<BTreeMap<String, (u64, u64)>> {
+        let symbols = self.symbols.try_lock()?;
+        let mut extents: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        if let Some(range) = kallsyms::text_range() {
+            extents.insert("kernel".to_string(), range);
+        }
+        for symbol in symbols.values() {
+            let end = symbol.address + symbol.size as u64;
+            let extent = extents.entry(symbol.module.clone()).or_insert((symbol.address, end));
+            extent.0 = extent.0.min(symbol.address);
+            extent.1 = extent.1.max(end);
+        }
+        Some(extents)
+    }
 }
 
 pub struct ResolvedSymbol {
@@ -252,42 +237,271 @@ impl ResolvedSymbol {
     }
 }
 
-// Kallsyms-like interface for runtime symbol loading
+// Kallsyms-like symbol table embedded in the kernel image
+//
+// The kernel reserves a fixed-size .kallsyms section which the post-link
+// step (tools/kallsyms) fills with the function symbols of the ELF symbol
+// table, sorted by address with prefix-compressed names, and the location
+// of .eh_frame and .eh_frame_hdr for the unwinder:
+//
+//   "KSYMTAB1" | table length u32 | symbol count u32 |
+//   .eh_frame address u64, size u64 | .eh_frame_hdr address u64, size u64
+//
+// followed by one entry per symbol: address delta from the previous symbol
+// (uleb128), size (uleb128), bytes shared with the previous name (u8),
+// suffix length (uleb128), suffix. Every RESTART_INTERVAL-th entry holds an
+// absolute address and a full name so decoding can start there.
 pub mod kallsyms {
     use super::*;
-    
-    pub fn load_runtime_symbols() {
-        crate::serial_println!("[KALLSYMS] Loading runtime symbols...");
-        
-        // In a real kernel, this would:
-        // 1. Read symbols from a compressed table in the kernel image
-        // 2. Decompress and parse the symbol data
-        // 3. Build the runtime symbol table
-        
-        // Add some example runtime symbols
-        SYMBOLS.add_symbol(Symbol {
-            address: 0x210000,
-            size: 0x50,
-            name: "schedule".to_string(),
-            module: "kernel".to_string(),
-            source_file: Some("src/process/scheduler.rs".to_string()),
-            line_number: None,
-            symbol_type: SymbolType::Function,
-        });
-        
-        SYMBOLS.add_symbol(Symbol {
-            address: 0x211000,
-            size: 0x100,
-            name: "do_page_fault".to_string(),
+    use spin::Once;
+
+    pub const CAPACITY: usize = 1024 * 1024;
+    pub const RESERVED_MAGIC: &[u8; 8] = b"KSYMRSVD";
+    pub const TABLE_MAGIC: &[u8; 8] = b"KSYMTAB1";
+    pub const HEADER_SIZE: usize = 48;
+    pub const RESTART_INTERVAL: usize = 64;
+    pub const MAX_NAME: usize = 255;
+
+    #[used]
+    #[link_section = ".kallsyms"]
+    static KALLSYMS: [u8; CAPACITY] = reserved();
+
+    const fn reserved() -> [u8; CAPACITY] {
+        let mut area = [0; CAPACITY];
+        let mut i = 0;
+        while i < RESERVED_MAGIC.len() {
+            area[i] = RESERVED_MAGIC[i];
+            i += 1;
+        }
+        area
+    }
+
+    // (address, table offset) of every restart entry, built at init so
+    // lookups on the panic path do not need to allocate it
+    static INDEX: Once<Vec<(u64, usize)>> = Once::new();
+
+    struct Header {
+        length: usize,
+        count: usize,
+        eh_frame: (u64, u64),
+        eh_frame_hdr: (u64, u64),
+    }
+
+    fn table() -> Option<(&'static [u8], Header)> {
+        // The section is rewritten after linking, so hide its compile-time
+        // contents from the optimizer
+        let base = core::hint::black_box(KALLSYMS.as_ptr());
+        let area = unsafe { core::slice::from_raw_parts(base, CAPACITY) };
+        if &area[..8] != TABLE_MAGIC {
+            return None;
+        }
+
+        let u32_at = |offset: usize| u32::from_le_bytes(area[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(area[offset..offset + 8].try_into().unwrap());
+        let header = Header {
+            length: u32_at(8) as usize,
+            count: u32_at(12) as usize,
+            eh_frame: (u64_at(16), u64_at(24)),
+            eh_frame_hdr: (u64_at(32), u64_at(40)),
+        };
+        if header.length < HEADER_SIZE || header.length > CAPACITY {
+            return None;
+        }
+        Some((&area[..header.length], header))
+    }
+
+    // Sequential decoder over the symbol entries
+    struct Cursor {
+        data: &'static [u8],
+        position: usize,
+        index: usize,
+        count: usize,
+        address: u64,
+        name: [u8; MAX_NAME],
+        name_length: usize,
+    }
+
+    impl Cursor {
+        fn new(data: &'static [u8], count: usize, position: usize, index: usize) -> Self {
+            Self {
+                data,
+                position,
+                index,
+                count,
+                address: 0,
+                name: [0; MAX_NAME],
+                name_length: 0,
+            }
+        }
+
+        fn uleb128(&mut self) -> Option<u64> {
+            let mut value = 0u64;
+            let mut shift = 0;
+            loop {
+                let byte = *self.data.get(self.position)?;
+                self.position += 1;
+                if shift < 64 {
+                    value |= ((byte & 0x7f) as u64) << shift;
+                }
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return Some(value);
+                }
+            }
+        }
+
+        // Decode the next entry as (address, size); its name is left in
+        // self.name
+        fn next(&mut self) -> Option<(u64, u64)> {
+            if self.index >= self.count {
+                return None;
+            }
+            let restart = self.index % RESTART_INTERVAL == 0;
+            let delta = self.uleb128()?;
+            self.address = if restart { delta } else { self.address.checked_add(delta)? };
+            let size = self.uleb128()?;
+
+            let shared = (*self.data.get(self.position)? as usize).min(self.name_length);
+            self.position += 1;
+            let suffix_length = self.uleb128()? as usize;
+            let suffix = self.data.get(self.position..self.position.checked_add(suffix_length)?)?;
+            self.position += suffix_length;
+
+            let length = (shared + suffix.len()).min(MAX_NAME);
+            self.name[shared..length].copy_from_slice(&suffix[..length - shared]);
+            self.name_length = length;
+            self.index += 1;
+            Some((self.address, size))
+        }
+
+        fn name(&self) -> &str {
+            core::str::from_utf8(&self.name[..self.name_length]).unwrap_or("<invalid>")
+        }
+    }
+
+    fn build_index(data: &'static [u8], count: usize) -> Vec<(u64, usize)> {
+        let mut index = Vec::with_capacity(count / RESTART_INTERVAL + 1);
+        let mut cursor = Cursor::new(data, count, HEADER_SIZE, 0);
+        loop {
+            let position = cursor.position;
+            let restart = cursor.index % RESTART_INTERVAL == 0;
+            match cursor.next() {
+                Some((address, _)) if restart => index.push((address, position)),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        index
+    }
+
+    fn symbol(address: u64, size: u64, name: &str) -> Symbol {
+        Symbol {
+            address,
+            size: size as usize,
+            name: name.to_string(),
             module: "kernel".to_string(),
-            source_file: Some("src/memory/paging.rs".to_string()),
+            source_file: None,
             line_number: None,
             symbol_type: SymbolType::Function,
-        });
-        
-        crate::serial_println!("[KALLSYMS] Loaded runtime symbols");
+        }
     }
-    
+
+    /// Kernel function containing `address`
+    pub fn lookup(address: u64) -> Option<ResolvedSymbol> {
+        let (data, header) = table()?;
+
+        // Start at the last restart entry at or below the address, or scan
+        // from the beginning if the index has not been built
+        let mut cursor = match INDEX.get() {
+            Some(index) => {
+                let slot = match index.binary_search_by_key(&address, |&(start, _)| start) {
+                    Ok(slot) => slot,
+                    Err(0) => return None,
+                    Err(slot) => slot - 1,
+                };
+                Cursor::new(data, header.count, index[slot].1, slot * RESTART_INTERVAL)
+            }
+            None => Cursor::new(data, header.count, HEADER_SIZE, 0),
+        };
+
+        let mut found = None;
+        while let Some((start, size)) = cursor.next() {
+            if start > address {
+                break;
+            }
+            if address < start.saturating_add(size) {
+                found = Some(symbol(start, size, cursor.name()));
+            }
+        }
+
+        found.map(|symbol| ResolvedSymbol {
+            offset: address - symbol.address,
+            symbol,
+        })
+    }
+
+    pub fn find(name: &str) -> Option<Symbol> {
+        let (data, header) = table()?;
+        let mut cursor = Cursor::new(data, header.count, HEADER_SIZE, 0);
+        while let Some((address, size)) = cursor.next() {
+            if cursor.name() == name {
+                return Some(symbol(address, size, name));
+            }
+        }
+        None
+    }
+
+    /// Address range covered by the kernel's own functions
+    pub fn text_range() -> Option<(u64, u64)> {
+        let (data, header) = table()?;
+        let mut cursor = Cursor::new(data, header.count, HEADER_SIZE, 0);
+        let (first, size) = cursor.next()?;
+        let mut end = first + size;
+        while let Some((address, size)) = cursor.next() {
+            end = end.max(address + size);
+        }
+        Some((first, end))
+    }
+
+    /// The kernel's .eh_frame for the unwinder's CFI fallback
+    pub fn eh_frame() -> Option<super::super::unwind::cfi::EhFrame> {
+        let (_, header) = table()?;
+        let (address, size) = header.eh_frame;
+        if address == 0 || size == 0 {
+            return None;
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(address as *const u8, size as usize) };
+        let (hdr_address, hdr_size) = header.eh_frame_hdr;
+        let hdr = if hdr_address != 0 && hdr_size != 0 {
+            Some((
+                unsafe { core::slice::from_raw_parts(hdr_address as *const u8, hdr_size as usize) },
+                hdr_address,
+            ))
+        } else {
+            None
+        };
+
+        Some(super::super::unwind::cfi::EhFrame { data, address, header: hdr })
+    }
+
+    pub fn load_runtime_symbols() {
+        crate::serial_println!("[KALLSYMS] Loading runtime symbols...");
+
+        match table() {
+            Some((data, header)) => {
+                INDEX.call_once(|| build_index(data, header.count));
+                crate::serial_println!("[KALLSYMS] {} kernel symbols ({} bytes), unwind info {}",
+                    header.count,
+                    header.length,
+                    if header.eh_frame.0 != 0 { "present" } else { "missing" });
+            }
+            None => {
+                crate::serial_println!("[KALLSYMS] No symbol table in the kernel image (tools/kallsyms was not run)");
+            }
+        }
+    }
+
     pub fn sprint_symbol(address: u64) -> String {
         SYMBOLS.format_address(address)
     }
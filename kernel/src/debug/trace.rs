@@ -1,444 +1,423 @@
 // Kernel Tracing Infrastructure
-// Static and dynamic tracepoints, event tracing, ring buffer
+// Static trace events declared by subsystems, dynamic probes (kprobes.rs),
+// and per-CPU ring buffers of binary event records
 
-use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-// Main tracing system
-pub struct TraceSystem {
-    enabled: AtomicBool,
-    event_buffer: Mutex<RingBuffer>,
-    tracepoints: Mutex<BTreeMap<String, Tracepoint>>,
-    filters: Mutex<Vec<TraceFilter>>,
-    statistics: TraceStatistics,
-}
-
-// Ring buffer for trace events
-struct RingBuffer {
-    buffer: Vec<TraceEvent>,
-    capacity: usize,
-    head: AtomicUsize,
-    tail: AtomicUsize,
-    overrun_count: AtomicU64,
-}
-
-// Individual trace event
-#[derive(Clone)]
-struct TraceEvent {
-    timestamp: u64,
-    cpu_id: u32,
-    pid: u32,
-    event_id: u32,
-    category: String,
-    name: String,
-    data: TraceData,
-    stack_depth: u32,
-}
+use crate::smp::MAX_CPUS;
 
-#[derive(Clone)]
-enum TraceData {
-    None,
-    U64(u64),
-    I64(i64),
-    String(String),
-    Binary(Vec<u8>),
-    Structured(BTreeMap<String, String>),
-}
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+pub const MAX_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_ARGS: usize = 8;
 
-// Static tracepoint definition
-struct Tracepoint {
-    id: u32,
-    name: String,
-    category: String,
+// Record layout, in the ring buffers and in saved traces (little-endian):
+//
+//   timestamp u64 (TSC) | pid u32 | event id u16 | payload length u16 | payload
+//
+// Static events and probes carry their arguments as u64s; message events
+// carry text.
+const RECORD_HEADER_SIZE: usize = 16;
+const MAX_MESSAGE: usize = 256;
+
+/// Event id of the free-form messages written by record_event and trace!
+pub const MESSAGE_EVENT: u16 = 0;
+const UNREGISTERED: u16 = u16::MAX;
+
+// Saved trace file:
+//
+//   "KTRACE01" | cpu count u32 | event count u32 | TSC frequency u64 |
+//   events: id u16, field count u8, "system:name\0", field names each "\0"
+//   per CPU: cpu u32, lost records u64, length u32, records oldest first
+pub const TRACE_FILE_MAGIC: &[u8; 8] = b"KTRACE01";
+
+/// A trace event. Subsystems declare these as statics with
+/// declare_trace_event! and register them in init(); probes create them at
+/// runtime.
+pub struct TraceEvent {
+    pub system: &'static str,
+    pub name: &'static str,
+    pub fields: &'static [&'static str],
+    id: AtomicU16,
     enabled: AtomicBool,
-    hit_count: AtomicU64,
-    format: String,
+    hits: AtomicU64,
 }
 
-// Trace filter for selective tracing
-struct TraceFilter {
-    category: Option<String>,
-    name: Option<String>,
-    pid: Option<u32>,
-    cpu: Option<u32>,
-    enabled: bool,
-}
+impl TraceEvent {
+    pub const fn new(system: &'static str, name: &'static str, fields: &'static [&'static str]) -> Self {
+        Self {
+            system,
+            name,
+            fields,
+            id: AtomicU16::new(UNREGISTERED),
+            enabled: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
 
-// Tracing statistics
-struct TraceStatistics {
-    total_events: AtomicU64,
-    dropped_events: AtomicU64,
-    categories: Mutex<BTreeMap<String, CategoryStats>>,
+    /// Record the event with one argument per field; a single load when the
+    /// event is off
+    #[inline]
+    pub fn emit(&self, args: &[u64]) {
+        if self.enabled() {
+            self.record(args);
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    fn record(&self, args: &[u64]) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let count = args.len().min(MAX_ARGS);
+        let mut payload = [0u8; MAX_ARGS * 8];
+        for (i, arg) in args[..count].iter().enumerate() {
+            payload[i * 8..i * 8 + 8].copy_from_slice(&arg.to_le_bytes());
+        }
+        write_record(self.id(), &payload[..count * 8]);
+    }
 }
 
-struct CategoryStats {
-    event_count: u64,
-    total_size: u64,
+/// Declare a static trace event:
+/// `declare_trace_event!(pub TRACE_IRQ_ENTRY, "irq", "irq_entry", ["vector"]);`
+#[macro_export]
+macro_rules! declare_trace_event {
+    ($vis:vis $ident:ident, $system:literal, $name:literal, [$($field:literal),* $(,)?]) => {
+        $vis static $ident: $crate::debug::trace::TraceEvent =
+            $crate::debug::trace::TraceEvent::new($system, $name, &[$($field),*]);
+    };
 }
 
-lazy_static! {
-    pub static ref TRACE: TraceSystem = TraceSystem::new();
+// Byte ring of whole records; the oldest records are dropped to make room
+struct Ring {
+    data: Vec<u8>,
+    // Monotonic byte positions; index into data modulo its length
+    head: u64,
+    tail: u64,
+    lost: u64,
 }
 
-impl TraceSystem {
-    pub fn new() -> Self {
+impl Ring {
+    const fn empty() -> Self {
         Self {
-            enabled: AtomicBool::new(false),
-            event_buffer: Mutex::new(RingBuffer::new(65536)),  // 64K events
-            tracepoints: Mutex::new(BTreeMap::new()),
-            filters: Mutex::new(Vec::new()),
-            statistics: TraceStatistics::new(),
+            data: Vec::new(),
+            head: 0,
+            tail: 0,
+            lost: 0,
         }
     }
-    
-    pub fn init(&self) {
-        // Register built-in tracepoints
-        self.register_builtin_tracepoints();
-        
-        self.enabled.store(true, Ordering::SeqCst);
-        crate::serial_println!("[TRACE] Tracing infrastructure initialized");
-    }
-    
-    fn register_builtin_tracepoints(&self) {
-        // Register common kernel tracepoints
-        self.register_tracepoint("scheduler", "context_switch");
-        self.register_tracepoint("scheduler", "wake_up");
-        self.register_tracepoint("scheduler", "sleep");
-        
-        self.register_tracepoint("mm", "page_alloc");
-        self.register_tracepoint("mm", "page_free");
-        self.register_tracepoint("mm", "page_fault");
-        self.register_tracepoint("mm", "kmalloc");
-        self.register_tracepoint("mm", "kfree");
-        
-        self.register_tracepoint("irq", "irq_entry");
-        self.register_tracepoint("irq", "irq_exit");
-        self.register_tracepoint("irq", "softirq_entry");
-        self.register_tracepoint("irq", "softirq_exit");
-        
-        self.register_tracepoint("syscall", "sys_enter");
-        self.register_tracepoint("syscall", "sys_exit");
-        
-        self.register_tracepoint("block", "bio_queue");
-        self.register_tracepoint("block", "bio_complete");
-        
-        self.register_tracepoint("net", "netif_receive");
-        self.register_tracepoint("net", "net_dev_xmit");
-        
-        self.register_tracepoint("lock", "lock_acquire");
-        self.register_tracepoint("lock", "lock_release");
-        self.register_tracepoint("lock", "lock_contended");
-    }
-    
-    pub fn register_tracepoint(&self, category: &str, name: &str) -> u32 {
-        let mut tracepoints = self.tracepoints.lock();
-        let id = tracepoints.len() as u32;
-        
-        let tp = Tracepoint {
-            id,
-            name: name.to_string(),
-            category: category.to_string(),
-            enabled: AtomicBool::new(false),
-            hit_count: AtomicU64::new(0),
-            format: String::new(),
-        };
-        
-        tracepoints.insert(format!("{}:{}", category, name), tp);
-        id
-    }
-    
-    pub fn enable_tracepoint(&self, category: &str, name: &str) {
-        let key = format!("{}:{}", category, name);
-        if let Some(tp) = self.tracepoints.lock().get(&key) {
-            tp.enabled.store(true, Ordering::SeqCst);
-            crate::serial_println!("[TRACE] Enabled tracepoint {}:{}", category, name);
+
+    fn reset(&mut self, size: usize) {
+        if self.data.len() != size {
+            self.data = alloc::vec![0; size];
         }
+        self.head = 0;
+        self.tail = 0;
+        self.lost = 0;
     }
-    
-    pub fn disable_tracepoint(&self, category: &str, name: &str) {
-        let key = format!("{}:{}", category, name);
-        if let Some(tp) = self.tracepoints.lock().get(&key) {
-            tp.enabled.store(false, Ordering::SeqCst);
-            crate::serial_println!("[TRACE] Disabled tracepoint {}:{}", category, name);
-        }
+
+    fn byte(&self, position: u64) -> u8 {
+        self.data[(position % self.data.len() as u64) as usize]
     }
-    
-    pub fn trace_event(&self, category: &str, name: &str, data: TraceData) {
-        if !self.enabled.load(Ordering::Relaxed) {
-            return;
-        }
-        
-        // Check if tracepoint is enabled
-        let key = format!("{}:{}", category, name);
-        let tracepoints = self.tracepoints.lock();
-        if let Some(tp) = tracepoints.get(&key) {
-            if !tp.enabled.load(Ordering::Relaxed) {
-                return;
-            }
-            tp.hit_count.fetch_add(1, Ordering::Relaxed);
-        }
-        drop(tracepoints);
-        
-        // Check filters
-        if !self.should_trace(category, name) {
+
+    fn write(&mut self, bytes: &[u8]) {
+        let size = self.data.len();
+        let start = (self.head % size as u64) as usize;
+        let first = bytes.len().min(size - start);
+        self.data[start..start + first].copy_from_slice(&bytes[..first]);
+        self.data[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.head += bytes.len() as u64;
+    }
+
+    fn record_length(&self, position: u64) -> u64 {
+        let length = self.byte(position + 14) as u64 | (self.byte(position + 15) as u64) << 8;
+        RECORD_HEADER_SIZE as u64 + length
+    }
+
+    fn push(&mut self, header: &[u8; RECORD_HEADER_SIZE], payload: &[u8]) {
+        let length = (RECORD_HEADER_SIZE + payload.len()) as u64;
+        if self.data.is_empty() || length > self.data.len() as u64 {
             return;
         }
-        
-        // Create trace event
-        let event = TraceEvent {
-            timestamp: self.get_timestamp(),
-            cpu_id: self.get_cpu_id(),
-            pid: self.get_current_pid(),
-            event_id: self.get_next_event_id(),
-            category: category.to_string(),
-            name: name.to_string(),
-            data,
-            stack_depth: self.get_stack_depth(),
-        };
-        
-        // Add to ring buffer
-        self.event_buffer.lock().push(event.clone());
-        
-        // Update statistics
-        self.statistics.total_events.fetch_add(1, Ordering::Relaxed);
-        self.statistics.update_category_stats(&event.category, 1);
-    }
-    
-    fn should_trace(&self, category: &str, name: &str) -> bool {
-        let filters = self.filters.lock();
-        
-        if filters.is_empty() {
-            return true;  // No filters = trace everything
-        }
-        
-        for filter in filters.iter() {
-            if !filter.enabled {
-                continue;
-            }
-            
-            let category_match = filter.category.as_ref()
-                .map_or(true, |c| c == category);
-            let name_match = filter.name.as_ref()
-                .map_or(true, |n| n == name);
-            
-            if category_match && name_match {
-                return true;
-            }
-        }
-        
-        false
-    }
-    
-    pub fn add_filter(&self, filter: TraceFilter) {
-        self.filters.lock().push(filter);
-        crate::serial_println!("[TRACE] Filter added");
-    }
-    
-    pub fn clear_filters(&self) {
-        self.filters.lock().clear();
-        crate::serial_println!("[TRACE] All filters cleared");
-    }
-    
-    pub fn dump_buffer(&self) -> Vec<TraceEvent> {
-        self.event_buffer.lock().dump()
-    }
-    
-    pub fn clear_buffer(&self) {
-        self.event_buffer.lock().clear();
-        crate::serial_println!("[TRACE] Buffer cleared");
-    }
-    
-    pub fn print_trace(&self, limit: usize) {
-        let events = self.dump_buffer();
-        let start = events.len().saturating_sub(limit);
-        
-        crate::serial_println!("\n=== Trace Events (last {}) ===", limit);
-        
-        for event in &events[start..] {
-            crate::serial_println!("[{:12}] CPU{} PID{:5} {}:{} {}",
-                event.timestamp,
-                event.cpu_id,
-                event.pid,
-                event.category,
-                event.name,
-                format_trace_data(&event.data)
-            );
-        }
-        
-        let buffer = self.event_buffer.lock();
-        if buffer.overrun_count.load(Ordering::Relaxed) > 0 {
-            crate::serial_println!("\nWarning: {} events lost due to buffer overrun",
-                buffer.overrun_count.load(Ordering::Relaxed));
+        while self.head + length - self.tail > self.data.len() as u64 {
+            self.tail += self.record_length(self.tail);
+            self.lost += 1;
         }
+        self.write(header);
+        self.write(payload);
     }
-    
-    pub fn print_statistics(&self) {
-        crate::serial_println!("\n=== Trace Statistics ===");
-        crate::serial_println!("Total events: {}", 
-            self.statistics.total_events.load(Ordering::Relaxed));
-        crate::serial_println!("Dropped events: {}",
-            self.statistics.dropped_events.load(Ordering::Relaxed));
-        
-        crate::serial_println!("\nEvents by category:");
-        let categories = self.statistics.categories.lock();
-        for (name, stats) in categories.iter() {
-            crate::serial_println!("  {}: {} events", name, stats.event_count);
-        }
-        
-        crate::serial_println!("\nTracepoint hit counts:");
-        let tracepoints = self.tracepoints.lock();
-        for (key, tp) in tracepoints.iter() {
-            let hits = tp.hit_count.load(Ordering::Relaxed);
-            if hits > 0 {
-                crate::serial_println!("  {}: {} hits", key, hits);
-            }
-        }
+
+    // Contents, oldest record first
+    fn contents(&self) -> Vec<u8> {
+        (self.tail..self.head).map(|position| self.byte(position)).collect()
     }
-    
-    fn get_timestamp(&self) -> u64 {
-        unsafe { core::arch::x86_64::_rdtsc() }
-    }
-    
-    fn get_cpu_id(&self) -> u32 {
-        0  // Would get actual CPU ID
-    }
-    
-    fn get_current_pid(&self) -> u32 {
-        0  // Would get current process ID
-    }
-    
-    fn get_next_event_id(&self) -> u32 {
-        static COUNTER: AtomicU64 = AtomicU64::new(0);
-        COUNTER.fetch_add(1, Ordering::Relaxed) as u32
-    }
-    
-    fn get_stack_depth(&self) -> u32 {
-        let mut depth = 0;
-        let mut rbp: u64;
-        
-        unsafe {
-            core::arch::asm!("mov {}, rbp", out(reg) rbp);
-        }
-        
-        while rbp != 0 && rbp > 0x1000 && depth < 32 {
-            unsafe {
-                rbp = *(rbp as *const u64);
+}
+
+// Recording state; events can be enabled while stopped and vice versa
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// Records not written because the CPU's ring was busy (re-entered from an
+// interrupt or probe while writing)
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static MESSAGES: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // Indexed by id - 1
+    static ref EVENTS: Mutex<Vec<&'static TraceEvent>> = Mutex::new(Vec::new());
+    static ref BUFFERS: Vec<Mutex<Ring>> = (0..MAX_CPUS).map(|_| Mutex::new(Ring::empty())).collect();
+}
+
+fn write_record(event: u16, payload: &[u8]) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let cpu = crate::smp::current_cpu_id() as usize;
+    // The interrupted code may hold the process manager; the pid is then
+    // not known
+    let pid = crate::process::PROCESS_MANAGER
+        .try_lock()
+        .and_then(|manager| manager.current_process)
+        .map_or(0, |pid| pid.0 as u32);
+
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    header[0..8].copy_from_slice(&crate::timer::rdtsc().to_le_bytes());
+    header[8..12].copy_from_slice(&pid.to_le_bytes());
+    header[12..14].copy_from_slice(&event.to_le_bytes());
+    header[14..16].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        match BUFFERS.get(cpu).and_then(|ring| ring.try_lock()) {
+            Some(mut ring) => ring.push(&header, payload),
+            None => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
             }
-            depth += 1;
         }
-        
-        depth
+    });
+}
+
+/// Make an event known to the tracer; returns its id
+pub fn register_event(event: &'static TraceEvent) -> u16 {
+    let mut events = EVENTS.lock();
+    if event.id() != UNREGISTERED {
+        return event.id();
     }
+    events.push(event);
+    let id = events.len() as u16;
+    event.id.store(id, Ordering::SeqCst);
+    id
 }
 
-impl RingBuffer {
-    fn new(capacity: usize) -> Self {
-        Self {
-            buffer: Vec::with_capacity(capacity),
-            capacity,
-            head: AtomicUsize::new(0),
-            tail: AtomicUsize::new(0),
-            overrun_count: AtomicU64::new(0),
+pub fn events() -> Vec<&'static TraceEvent> {
+    EVENTS.lock().clone()
+}
+
+fn event_by_id(events: &[&'static TraceEvent], id: u16) -> Option<&'static TraceEvent> {
+    events.get((id as usize).checked_sub(1)?).copied()
+}
+
+/// Enable or disable events matching "all", "system" or "system:name";
+/// returns how many matched
+pub fn set_events_enabled(pattern: &str, enabled: bool) -> usize {
+    let (system, name) = match pattern.split_once(':') {
+        Some((system, name)) => (system, Some(name)),
+        None => (pattern, None),
+    };
+
+    let mut matched = 0;
+    for event in EVENTS.lock().iter() {
+        let system_match = system == "all" || event.system == system;
+        let name_match = name.map_or(true, |name| event.name == name);
+        if system_match && name_match {
+            event.set_enabled(enabled);
+            matched += 1;
         }
     }
-    
-    fn push(&mut self, event: TraceEvent) {
-        let head = self.head.load(Ordering::Acquire);
-        let tail = self.tail.load(Ordering::Acquire);
-        
-        let next_head = (head + 1) % self.capacity;
-        
-        if next_head == tail {
-            // Buffer full, overwrite oldest
-            self.overrun_count.fetch_add(1, Ordering::Relaxed);
-            self.tail.store((tail + 1) % self.capacity, Ordering::Release);
-        }
-        
-        if self.buffer.len() <= head {
-            self.buffer.resize(head + 1, TraceEvent::default());
-        }
-        
-        self.buffer[head] = event;
-        self.head.store(next_head, Ordering::Release);
-    }
-    
-    fn dump(&self) -> Vec<TraceEvent> {
-        let mut result = Vec::new();
-        let head = self.head.load(Ordering::Acquire);
-        let tail = self.tail.load(Ordering::Acquire);
-        
-        let mut idx = tail;
-        while idx != head {
-            if idx < self.buffer.len() {
-                result.push(self.buffer[idx].clone());
-            }
-            idx = (idx + 1) % self.capacity;
+    matched
+}
+
+/// Start recording into fresh per-CPU buffers of `size` bytes each
+pub fn start(size: usize) -> Result<(), &'static str> {
+    if size < 4096 || size > MAX_BUFFER_SIZE {
+        return Err("buffer size out of range");
+    }
+
+    RECORDING.store(false, Ordering::SeqCst);
+    let mut cpus = crate::smp::SMP_MANAGER.lock().get_online_cpus();
+    let current = crate::smp::current_cpu_id();
+    if !cpus.contains(&current) {
+        cpus.push(current);
+    }
+    for cpu in cpus {
+        if let Some(ring) = BUFFERS.get(cpu as usize) {
+            ring.lock().reset(size);
         }
-        
-        result
     }
-    
-    fn clear(&mut self) {
-        self.head.store(0, Ordering::Release);
-        self.tail.store(0, Ordering::Release);
-        self.overrun_count.store(0, Ordering::Release);
-        self.buffer.clear();
+
+    BUFFER_SIZE.store(size, Ordering::SeqCst);
+    if TSC_FREQUENCY.load(Ordering::Relaxed) == 0 {
+        TSC_FREQUENCY.store(crate::timer::get_tsc_frequency(), Ordering::SeqCst);
     }
+    DROPPED.store(0, Ordering::SeqCst);
+    RECORDING.store(true, Ordering::SeqCst);
+    crate::serial_println!("[TRACE] Recording started ({} KiB per CPU)", size / 1024);
+    Ok(())
 }
 
-impl TraceStatistics {
-    fn new() -> Self {
-        Self {
-            total_events: AtomicU64::new(0),
-            dropped_events: AtomicU64::new(0),
-            categories: Mutex::new(BTreeMap::new()),
+pub fn stop() {
+    RECORDING.store(false, Ordering::SeqCst);
+    crate::serial_println!("[TRACE] Recording stopped");
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.load(Ordering::Relaxed)
+}
+
+pub fn buffer_size() -> usize {
+    BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Empty every buffer, keeping its allocation
+pub fn clear() {
+    for ring in BUFFERS.iter() {
+        let mut ring = ring.lock();
+        let size = ring.data.len();
+        ring.reset(size);
+    }
+    DROPPED.store(0, Ordering::SeqCst);
+}
+
+// (cpu, lost records, contents) of every allocated buffer
+fn snapshot() -> Vec<(u32, u64, Vec<u8>)> {
+    BUFFERS
+        .iter()
+        .enumerate()
+        .filter_map(|(cpu, ring)| {
+            let ring = ring.lock();
+            if ring.data.is_empty() {
+                None
+            } else {
+                Some((cpu as u32, ring.lost, ring.contents()))
+            }
+        })
+        .collect()
+}
+
+/// A decoded record
+pub struct Record {
+    pub cpu: u32,
+    pub timestamp: u64,
+    pub pid: u32,
+    pub event: u16,
+    pub payload: Vec<u8>,
+}
+
+fn parse_records(cpu: u32, data: &[u8], records: &mut Vec<Record>) {
+    let mut offset = 0;
+    while offset + RECORD_HEADER_SIZE <= data.len() {
+        let header = &data[offset..offset + RECORD_HEADER_SIZE];
+        let length = u16::from_le_bytes([header[14], header[15]]) as usize;
+        let end = offset + RECORD_HEADER_SIZE + length;
+        if end > data.len() {
+            break;
         }
+        records.push(Record {
+            cpu,
+            timestamp: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+            pid: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            event: u16::from_le_bytes([header[12], header[13]]),
+            payload: data[offset + RECORD_HEADER_SIZE..end].to_vec(),
+        });
+        offset = end;
     }
-    
-    fn update_category_stats(&self, category: &str, count: u64) {
-        let mut categories = self.categories.lock();
-        let stats = categories.entry(category.to_string())
-            .or_insert_with(|| CategoryStats {
-                event_count: 0,
-                total_size: 0,
-            });
-        stats.event_count += count;
+}
+
+/// All buffered records, merged across CPUs in timestamp order
+pub fn records() -> Vec<Record> {
+    let mut records = Vec::new();
+    for (cpu, _, data) in snapshot() {
+        parse_records(cpu, &data, &mut records);
     }
+    records.sort_by_key(|record| record.timestamp);
+    records
 }
 
-impl Default for TraceEvent {
-    fn default() -> Self {
-        Self {
-            timestamp: 0,
-            cpu_id: 0,
-            pid: 0,
-            event_id: 0,
-            category: String::new(),
-            name: String::new(),
-            data: TraceData::None,
-            stack_depth: 0,
+pub fn format_record(record: &Record, events: &[&'static TraceEvent]) -> String {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed).max(1);
+    let micros = (record.timestamp as u128 * 1_000_000 / frequency as u128) as u64;
+    let mut line = format!("[{:03}] {:>6}.{:06} pid {:<5} ",
+        record.cpu, micros / 1_000_000, micros % 1_000_000, record.pid);
+
+    if record.event == MESSAGE_EVENT {
+        line.push_str(&String::from_utf8_lossy(&record.payload));
+        return line;
+    }
+
+    match event_by_id(events, record.event) {
+        Some(event) => {
+            line.push_str(&format!("{}:{}:", event.system, event.name));
+            for (i, value) in record.payload.chunks_exact(8).enumerate() {
+                let value = u64::from_le_bytes(value.try_into().unwrap());
+                let field = event.fields.get(i).copied().unwrap_or("arg");
+                line.push_str(&format!(" {}={:#x}", field, value));
+            }
         }
+        None => line.push_str(&format!("event#{} ({} bytes)", record.event, record.payload.len())),
     }
+    line
 }
 
-fn format_trace_data(data: &TraceData) -> String {
-    match data {
-        TraceData::None => String::new(),
-        TraceData::U64(v) => format!("value={}", v),
-        TraceData::I64(v) => format!("value={}", v),
-        TraceData::String(s) => s.clone(),
-        TraceData::Binary(b) => format!("binary[{}]", b.len()),
-        TraceData::Structured(map) => {
-            let items: Vec<String> = map.iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect();
-            items.join(" ")
+/// Serialize the buffers and the event table into the saved trace format
+pub fn save() -> Vec<u8> {
+    let events = events();
+    let buffers = snapshot();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(TRACE_FILE_MAGIC);
+    out.extend_from_slice(&(buffers.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(events.len() as u32).to_le_bytes());
+    out.extend_from_slice(&TSC_FREQUENCY.load(Ordering::Relaxed).to_le_bytes());
+
+    for event in &events {
+        out.extend_from_slice(&event.id().to_le_bytes());
+        out.push(event.fields.len() as u8);
+        out.extend_from_slice(event.system.as_bytes());
+        out.push(b':');
+        out.extend_from_slice(event.name.as_bytes());
+        out.push(0);
+        for field in event.fields {
+            out.extend_from_slice(field.as_bytes());
+            out.push(0);
         }
     }
+
+    for (cpu, lost, data) in buffers {
+        out.extend_from_slice(&cpu.to_le_bytes());
+        out.extend_from_slice(&lost.to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&data);
+    }
+    out
 }
 
 // Trace macros for easy instrumentation
@@ -471,10 +450,9 @@ pub struct FunctionTracer {
 
 impl FunctionTracer {
     pub fn new(category: &str, name: &str) -> Self {
-        let start_time = unsafe { core::arch::x86_64::_rdtsc() };
-        
-        TRACE.trace_event(category, &format!("{}_enter", name), TraceData::None);
-        
+        let start_time = crate::timer::rdtsc();
+        record_event(category, &format!("{}_enter", name), "");
+
         Self {
             category: category.to_string(),
             name: name.to_string(),
@@ -485,41 +463,86 @@ impl FunctionTracer {
 
 impl Drop for FunctionTracer {
     fn drop(&mut self) {
-        let end_time = unsafe { core::arch::x86_64::_rdtsc() };
-        let duration = end_time - self.start_time;
-        
-        TRACE.trace_event(&self.category, &format!("{}_exit", self.name), 
-            TraceData::U64(duration));
+        let duration = crate::timer::rdtsc() - self.start_time;
+        trace_value(&self.category, &format!("{}_exit", self.name), duration);
     }
 }
 
+// Events declared by the instrumented subsystems
+fn builtin_events() -> [&'static TraceEvent; 7] {
+    [
+        &crate::process::smp_scheduler::TRACE_SCHED_SWITCH,
+        &crate::interrupts::TRACE_IRQ_ENTRY,
+        &crate::interrupts::TRACE_IRQ_EXIT,
+        &crate::drivers::storage::TRACE_BLOCK_RQ_ISSUE,
+        &crate::drivers::storage::TRACE_BLOCK_RQ_COMPLETE,
+        &crate::syscall::TRACE_SYS_ENTER,
+        &crate::syscall::TRACE_SYS_EXIT,
+    ]
+}
+
 // Public API
 pub fn init() {
-    TRACE.init();
+    for event in builtin_events() {
+        register_event(event);
+    }
+    crate::serial_println!("[TRACE] Tracing infrastructure initialized ({} events)", EVENTS.lock().len());
 }
 
+/// Free-form message event, recorded while tracing is on
 pub fn record_event(category: &str, name: &str, data: &str) {
-    TRACE.trace_event(category, name, TraceData::String(data.to_string()));
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    MESSAGES.fetch_add(1, Ordering::Relaxed);
+
+    let message = if data.is_empty() {
+        format!("{}:{}", category, name)
+    } else {
+        format!("{}:{}: {}", category, name, data)
+    };
+    let mut end = message.len().min(MAX_MESSAGE);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    write_record(MESSAGE_EVENT, &message.as_bytes()[..end]);
 }
 
 pub fn trace_value(category: &str, name: &str, value: u64) {
-    TRACE.trace_event(category, name, TraceData::U64(value));
+    if RECORDING.load(Ordering::Relaxed) {
+        record_event(category, name, &format!("value={}", value));
+    }
 }
 
 pub fn enable_category(category: &str) {
-    TRACE.add_filter(TraceFilter {
-        category: Some(category.to_string()),
-        name: None,
-        pid: None,
-        cpu: None,
-        enabled: true,
-    });
+    set_events_enabled(category, true);
 }
 
 pub fn print_trace(limit: usize) {
-    TRACE.print_trace(limit);
+    let events = events();
+    let records = records();
+    let start = records.len().saturating_sub(limit);
+
+    crate::serial_println!("\n=== Trace Events (last {}) ===", limit);
+    for record in &records[start..] {
+        crate::serial_println!("{}", format_record(record, &events));
+    }
 }
 
 pub fn print_statistics() {
-    TRACE.print_statistics();
-}
\ No newline at end of file
+    crate::serial_println!("\n=== Trace Statistics ===");
+    crate::serial_println!("Recording: {}", if is_recording() { "on" } else { "off" });
+    crate::serial_println!("Messages: {}", MESSAGES.load(Ordering::Relaxed));
+    crate::serial_println!("Dropped (buffer busy): {}", DROPPED.load(Ordering::Relaxed));
+
+    for (cpu, lost, data) in snapshot() {
+        crate::serial_println!("CPU{}: {} bytes buffered, {} records overwritten", cpu, data.len(), lost);
+    }
+
+    crate::serial_println!("\nEvent hit counts:");
+    for event in events() {
+        if event.hits() > 0 {
+            crate::serial_println!("  {}:{}: {} hits", event.system, event.name, event.hits());
+        }
+    }
+}
@@ -0,0 +1,750 @@
+// Stack Unwinding
+// Walks the saved frame pointer chain (the kernel is built with frame
+// pointers always on) and falls back to the DWARF call frame information in
+// .eh_frame where the chain is broken: leaf code without a frame, a frame
+// caught in its prologue, or a clobbered rbp.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::symbols;
+
+pub const MAX_FRAMES: usize = 64;
+
+// A frame pointer further than this above the stack pointer is not trusted
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub pc: u64,
+    pub sp: u64,
+    pub fp: u64,
+    /// pc is a return address, so the call itself is the instruction before it
+    pub return_address: bool,
+}
+
+impl Frame {
+    pub fn new(pc: u64, sp: u64, fp: u64) -> Self {
+        Self { pc, sp, fp, return_address: false }
+    }
+
+    // Address used for symbol and CFI lookup: inside the call instruction
+    // for return addresses, so a call at the very end of a function is
+    // attributed to that function
+    fn lookup_pc(&self) -> u64 {
+        if self.return_address {
+            self.pc - 1
+        } else {
+            self.pc
+        }
+    }
+
+    /// "function+0xoffset", or "<unknown>" when no symbol covers the pc
+    pub fn symbol(&self) -> String {
+        match symbols::SYMBOLS.resolve(self.lookup_pc()) {
+            Some(resolved) => {
+                let offset = resolved.offset + (self.pc - self.lookup_pc());
+                if resolved.symbol.module == "kernel" {
+                    format!("{}+{:#x}", resolved.symbol.name, offset)
+                } else {
+                    format!("{}!{}+{:#x}", resolved.symbol.module, resolved.symbol.name, offset)
+                }
+            }
+            None => String::from("<unknown>"),
+        }
+    }
+}
+
+/// Unwind the stack of the caller of this function
+#[inline(never)]
+pub fn current_frames(max: usize) -> Vec<Frame> {
+    let (pc, sp, fp): (u64, u64, u64);
+    unsafe {
+        core::arch::asm!(
+            "lea {}, [rip]",
+            "mov {}, rsp",
+            "mov {}, rbp",
+            out(reg) pc,
+            out(reg) sp,
+            out(reg) fp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    // Drop this function's own frame
+    let mut frames = backtrace(Frame::new(pc, sp, fp), max + 1);
+    if !frames.is_empty() {
+        frames.remove(0);
+    }
+    frames
+}
+
+/// Frame pointer of the code an exception handler interrupted. Must be
+/// inlined into the handler itself, whose prologue pushed it.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    read_u64(rbp).unwrap_or(0)
+}
+
+/// Unwind from an arbitrary register state, starting with that frame
+pub fn backtrace(start: Frame, max: usize) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut frame = start;
+
+    while frames.len() < max {
+        frames.push(frame);
+        let next = match step(&frame) {
+            Some(next) => next,
+            None => break,
+        };
+        // Stacks grow down, so each caller's frame sits above its callee
+        if next.pc == 0 || !is_canonical(next.pc) || next.sp <= frame.sp {
+            break;
+        }
+        frame = next;
+    }
+
+    frames
+}
+
+/// Return addresses along the frame pointer chain above `start`, innermost
+/// first. Does not allocate, for use from interrupt and NMI handlers;
+/// returns how many were stored.
+pub fn frame_pointer_chain(start: Frame, out: &mut [u64]) -> usize {
+    let mut frame = start;
+    let mut depth = 0;
+    while depth < out.len() {
+        let caller = match step_frame_pointer(&frame) {
+            Some(caller) => caller,
+            None => break,
+        };
+        if caller.pc == 0 || !is_canonical(caller.pc) {
+            break;
+        }
+        out[depth] = caller.pc;
+        depth += 1;
+        frame = caller;
+    }
+    depth
+}
+
+/// One "#n  address  function+offset" line per frame
+pub fn format_frames(frames: &[Frame]) -> Vec<String> {
+    frames
+        .iter()
+        .enumerate()
+        .map(|(index, frame)| format!("#{:<2} [{:#018x}] {}", index, frame.pc, frame.symbol()))
+        .collect()
+}
+
+pub fn print_backtrace(frames: &[Frame]) {
+    for line in format_frames(frames) {
+        crate::serial_println!("  {}", line);
+    }
+}
+
+fn step(frame: &Frame) -> Option<Frame> {
+    step_frame_pointer(frame).or_else(|| step_cfi(frame))
+}
+
+// Standard prologue: push rbp; mov rbp, rsp. [rbp] holds the caller's rbp
+// and [rbp + 8] the return address.
+fn step_frame_pointer(frame: &Frame) -> Option<Frame> {
+    let fp = frame.fp;
+    if fp == 0 || fp % 8 != 0 || fp < frame.sp || fp - frame.sp > MAX_FRAME_SIZE {
+        return None;
+    }
+
+    let caller_fp = read_u64(fp)?;
+    let return_address = read_u64(fp + 8)?;
+    if caller_fp != 0 && caller_fp <= fp {
+        return None;
+    }
+
+    Some(Frame {
+        pc: return_address,
+        sp: fp + 16,
+        fp: caller_fp,
+        return_address: true,
+    })
+}
+
+fn step_cfi(frame: &Frame) -> Option<Frame> {
+    let eh_frame = symbols::kallsyms::eh_frame()?;
+    let fde = cfi::find_fde(&eh_frame, frame.lookup_pc())?;
+    let row = cfi::unwind_row(&fde, frame.lookup_pc())?;
+
+    let cfa_base = match row.cfa_register {
+        cfi::RSP => frame.sp,
+        cfi::RBP => frame.fp,
+        _ => return None,
+    };
+    let cfa = (cfa_base as i64).wrapping_add(row.cfa_offset) as u64;
+
+    let return_address = match row.rules[fde.cie.return_register as usize] {
+        cfi::Rule::Offset(offset) => read_u64((cfa as i64).wrapping_add(offset) as u64)?,
+        _ => return None,
+    };
+    let caller_fp = match row.rules[cfi::RBP as usize] {
+        cfi::Rule::Offset(offset) => read_u64((cfa as i64).wrapping_add(offset) as u64)?,
+        cfi::Rule::ValOffset(offset) => (cfa as i64).wrapping_add(offset) as u64,
+        _ => frame.fp,
+    };
+
+    Some(Frame {
+        pc: return_address,
+        sp: cfa,
+        fp: caller_fp,
+        return_address: true,
+    })
+}
+
+fn is_canonical(address: u64) -> bool {
+    address < 0x0000_8000_0000_0000 || address >= 0xffff_8000_0000_0000
+}
+
+fn read_u64(address: u64) -> Option<u64> {
+    if address % 8 != 0 || !is_canonical(address) || !super::is_mapped(address) {
+        return None;
+    }
+    Some(unsafe { core::ptr::read_volatile(address as *const u64) })
+}
+
+// .eh_frame parsing and CFA program evaluation for x86_64
+pub mod cfi {
+    // DWARF register numbers
+    pub const RBP: u16 = 6;
+    pub const RSP: u16 = 7;
+    const REGISTER_COUNT: usize = 17; // rax..r15 plus the return address column
+
+    const MAX_REMEMBERED: usize = 8;
+
+    /// .eh_frame as loaded in memory, with its optional lookup table
+    pub struct EhFrame {
+        pub data: &'static [u8],
+        pub address: u64,
+        pub header: Option<(&'static [u8], u64)>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Rule {
+        Undefined,
+        SameValue,
+        Offset(i64),
+        ValOffset(i64),
+        Register(u16),
+        Unsupported,
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Row {
+        pub cfa_register: u16,
+        pub cfa_offset: i64,
+        pub rules: [Rule; REGISTER_COUNT],
+    }
+
+    pub struct Cie {
+        code_align: u64,
+        data_align: i64,
+        pub return_register: u16,
+        fde_encoding: u8,
+        augmented: bool,
+        instructions: &'static [u8],
+    }
+
+    pub struct Fde {
+        pub cie: Cie,
+        pub start: u64,
+        pub end: u64,
+        instructions: &'static [u8],
+    }
+
+    // Pointer encodings
+    const DW_EH_PE_OMIT: u8 = 0xff;
+    const DW_EH_PE_PCREL: u8 = 0x10;
+    const DW_EH_PE_DATAREL: u8 = 0x30;
+    const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+    struct Reader {
+        data: &'static [u8],
+        position: usize,
+        // Address of data[0], for pc-relative pointers
+        address: u64,
+    }
+
+    impl Reader {
+        fn new(data: &'static [u8], address: u64) -> Self {
+            Self { data, position: 0, address }
+        }
+
+        fn at_end(&self) -> bool {
+            self.position >= self.data.len()
+        }
+
+        fn bytes(&mut self, count: usize) -> Option<&'static [u8]> {
+            let end = self.position.checked_add(count)?;
+            let bytes = self.data.get(self.position..end)?;
+            self.position = end;
+            Some(bytes)
+        }
+
+        fn u8(&mut self) -> Option<u8> {
+            Some(self.bytes(1)?[0])
+        }
+
+        fn u16(&mut self) -> Option<u16> {
+            Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+        }
+
+        fn u32(&mut self) -> Option<u32> {
+            Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+        }
+
+        fn u64(&mut self) -> Option<u64> {
+            Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+        }
+
+        fn uleb128(&mut self) -> Option<u64> {
+            let mut value = 0u64;
+            let mut shift = 0;
+            loop {
+                let byte = self.u8()?;
+                if shift < 64 {
+                    value |= ((byte & 0x7f) as u64) << shift;
+                }
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return Some(value);
+                }
+            }
+        }
+
+        fn sleb128(&mut self) -> Option<i64> {
+            let mut value = 0i64;
+            let mut shift = 0;
+            loop {
+                let byte = self.u8()?;
+                if shift < 64 {
+                    value |= ((byte & 0x7f) as i64) << shift;
+                }
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    if shift < 64 && byte & 0x40 != 0 {
+                        value |= -1i64 << shift;
+                    }
+                    return Some(value);
+                }
+            }
+        }
+
+        fn cstr(&mut self) -> Option<&'static [u8]> {
+            let length = self.data.get(self.position..)?.iter().position(|&b| b == 0)?;
+            let string = self.bytes(length)?;
+            self.position += 1;
+            Some(string)
+        }
+
+        fn pointer(&mut self, encoding: u8, data_base: u64) -> Option<u64> {
+            if encoding == DW_EH_PE_OMIT {
+                return Some(0);
+            }
+            let field = self.address + self.position as u64;
+            let value = match encoding & 0x0f {
+                0x00 | 0x04 => self.u64()?,
+                0x01 => self.uleb128()?,
+                0x02 => self.u16()? as u64,
+                0x03 => self.u32()? as u64,
+                0x09 => self.sleb128()? as u64,
+                0x0a => self.u16()? as i16 as i64 as u64,
+                0x0b => self.u32()? as i32 as i64 as u64,
+                0x0c => self.u64()?,
+                _ => return None,
+            };
+            let value = match encoding & 0x70 {
+                0x00 => value,
+                DW_EH_PE_PCREL => field.wrapping_add(value),
+                DW_EH_PE_DATAREL => data_base.wrapping_add(value),
+                _ => return None,
+            };
+            if encoding & DW_EH_PE_INDIRECT != 0 {
+                return super::read_u64(value);
+            }
+            Some(value)
+        }
+    }
+
+    // Reader over one length-prefixed CIE/FDE record, positioned after the
+    // length, plus the offset of the record that follows
+    fn record(eh_frame: &EhFrame, offset: usize) -> Option<(Reader, usize)> {
+        let mut reader = Reader::new(eh_frame.data, eh_frame.address);
+        reader.position = offset;
+        let mut length = reader.u32()? as u64;
+        if length == 0xffff_ffff {
+            length = reader.u64()?;
+        }
+        let start = reader.position;
+        let end = start.checked_add(length as usize)?;
+        if length == 0 || end > eh_frame.data.len() {
+            return None;
+        }
+        Some((
+            Reader {
+                data: &eh_frame.data[..end],
+                position: start,
+                address: eh_frame.address,
+            },
+            end,
+        ))
+    }
+
+    fn parse_cie(eh_frame: &EhFrame, offset: usize) -> Option<Cie> {
+        let (mut reader, _) = record(eh_frame, offset)?;
+        if reader.u32()? != 0 {
+            return None;
+        }
+
+        let version = reader.u8()?;
+        let augmentation = reader.cstr()?;
+        let code_align = reader.uleb128()?;
+        let data_align = reader.sleb128()?;
+        let return_register = if version == 1 {
+            reader.u8()? as u16
+        } else {
+            reader.uleb128()? as u16
+        };
+        if return_register as usize >= REGISTER_COUNT {
+            return None;
+        }
+
+        let mut cie = Cie {
+            code_align,
+            data_align,
+            return_register,
+            fde_encoding: 0,
+            augmented: false,
+            instructions: &[],
+        };
+
+        if augmentation.first() == Some(&b'z') {
+            cie.augmented = true;
+            let length = reader.uleb128()? as usize;
+            let end = reader.position + length;
+            for &kind in &augmentation[1..] {
+                match kind {
+                    b'R' => cie.fde_encoding = reader.u8()?,
+                    b'P' => {
+                        let encoding = reader.u8()?;
+                        reader.pointer(encoding & !DW_EH_PE_INDIRECT, 0)?;
+                    }
+                    b'L' => {
+                        reader.u8()?;
+                    }
+                    _ => {}
+                }
+            }
+            reader.position = end;
+        } else if !augmentation.is_empty() {
+            return None;
+        }
+
+        cie.instructions = reader.bytes(reader.data.len().checked_sub(reader.position)?)?;
+        Some(cie)
+    }
+
+    // Parse the FDE at `offset`; None for CIEs and malformed records
+    fn parse_fde(eh_frame: &EhFrame, offset: usize) -> Option<Fde> {
+        let (mut reader, _) = record(eh_frame, offset)?;
+        let id_position = reader.position;
+        let cie_pointer = reader.u32()? as usize;
+        if cie_pointer == 0 {
+            return None;
+        }
+        let cie = parse_cie(eh_frame, id_position.checked_sub(cie_pointer)?)?;
+
+        let start = reader.pointer(cie.fde_encoding, 0)?;
+        let length = reader.pointer(cie.fde_encoding & 0x0f, 0)?;
+        if cie.augmented {
+            let skip = reader.uleb128()? as usize;
+            reader.bytes(skip)?;
+        }
+        let instructions = reader.bytes(reader.data.len().checked_sub(reader.position)?)?;
+
+        Some(Fde {
+            cie,
+            start,
+            end: start.wrapping_add(length),
+            instructions,
+        })
+    }
+
+    /// Find the FDE covering `pc`, through the binary search table in
+    /// .eh_frame_hdr when there is one and by a linear scan otherwise
+    pub fn find_fde(eh_frame: &EhFrame, pc: u64) -> Option<Fde> {
+        if let Some(fde) = search_header(eh_frame, pc) {
+            return Some(fde);
+        }
+
+        let mut offset = 0;
+        while offset + 4 <= eh_frame.data.len() {
+            let (_, next) = record(eh_frame, offset)?;
+            if let Some(fde) = parse_fde(eh_frame, offset) {
+                if pc >= fde.start && pc < fde.end {
+                    return Some(fde);
+                }
+            }
+            offset = next;
+        }
+        None
+    }
+
+    fn search_header(eh_frame: &EhFrame, pc: u64) -> Option<Fde> {
+        let (data, address) = eh_frame.header?;
+        let mut reader = Reader::new(data, address);
+        if reader.u8()? != 1 {
+            return None;
+        }
+        let frame_encoding = reader.u8()?;
+        let count_encoding = reader.u8()?;
+        let table_encoding = reader.u8()?;
+        reader.pointer(frame_encoding, address)?;
+        let count = reader.pointer(count_encoding, address)? as usize;
+
+        // Sorted (initial location, FDE address) pairs, datarel sdata4
+        if table_encoding != (DW_EH_PE_DATAREL | 0x0b) || count == 0 {
+            return None;
+        }
+        let table = reader.position;
+        let entry = |index: usize| -> Option<(u64, u64)> {
+            let mut reader = Reader::new(data, address);
+            reader.position = table + index * 8;
+            Some((
+                reader.pointer(table_encoding, address)?,
+                reader.pointer(table_encoding, address)?,
+            ))
+        };
+
+        let (mut low, mut high) = (0, count);
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if entry(middle)?.0 <= pc {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        let (start, fde_address) = entry(low)?;
+        if pc < start || fde_address < eh_frame.address {
+            return None;
+        }
+        let fde = parse_fde(eh_frame, (fde_address - eh_frame.address) as usize)?;
+        if pc >= fde.start && pc < fde.end {
+            Some(fde)
+        } else {
+            None
+        }
+    }
+
+    /// Run the CIE and FDE programs up to `pc` and return the resulting row
+    pub fn unwind_row(fde: &Fde, pc: u64) -> Option<Row> {
+        let mut row = Row {
+            cfa_register: RSP,
+            cfa_offset: 8,
+            rules: [Rule::Undefined; REGISTER_COUNT],
+        };
+        let mut location = fde.start;
+
+        execute(&fde.cie, fde.cie.instructions, &mut row, None, &mut location, u64::MAX)?;
+        let initial = row;
+        execute(&fde.cie, fde.instructions, &mut row, Some(&initial), &mut location, pc)?;
+        Some(row)
+    }
+
+    fn execute(
+        cie: &Cie,
+        instructions: &'static [u8],
+        row: &mut Row,
+        initial: Option<&Row>,
+        location: &mut u64,
+        pc: u64,
+    ) -> Option<()> {
+        // The programs are read in place, so pc-relative operands resolve
+        let mut reader = Reader::new(instructions, instructions.as_ptr() as u64);
+        let mut remembered = [*row; MAX_REMEMBERED];
+        let mut depth = 0;
+
+        let set = |row: &mut Row, register: u64, rule: Rule| {
+            if (register as usize) < REGISTER_COUNT {
+                row.rules[register as usize] = rule;
+            }
+        };
+        let restore = |row: &mut Row, register: u64| {
+            if let Some(initial) = initial {
+                if (register as usize) < REGISTER_COUNT {
+                    row.rules[register as usize] = initial.rules[register as usize];
+                }
+            }
+        };
+
+        while !reader.at_end() {
+            let opcode = reader.u8()?;
+            let operand = (opcode & 0x3f) as u64;
+
+            let advance = match opcode >> 6 {
+                1 => Some(operand),
+                2 => {
+                    let offset = reader.uleb128()? as i64 * cie.data_align;
+                    set(row, operand, Rule::Offset(offset));
+                    None
+                }
+                3 => {
+                    restore(row, operand);
+                    None
+                }
+                _ => match opcode {
+                    0x00 => None,
+                    0x01 => {
+                        // DW_CFA_set_loc
+                        let target = reader.pointer(cie.fde_encoding, 0)?;
+                        if target > pc {
+                            return Some(());
+                        }
+                        *location = target;
+                        None
+                    }
+                    0x02 => Some(reader.u8()? as u64),
+                    0x03 => Some(reader.u16()? as u64),
+                    0x04 => Some(reader.u32()? as u64),
+                    0x05 => {
+                        let register = reader.uleb128()?;
+                        let offset = reader.uleb128()? as i64 * cie.data_align;
+                        set(row, register, Rule::Offset(offset));
+                        None
+                    }
+                    0x06 => {
+                        let register = reader.uleb128()?;
+                        restore(row, register);
+                        None
+                    }
+                    0x07 => {
+                        let register = reader.uleb128()?;
+                        set(row, register, Rule::Undefined);
+                        None
+                    }
+                    0x08 => {
+                        let register = reader.uleb128()?;
+                        set(row, register, Rule::SameValue);
+                        None
+                    }
+                    0x09 => {
+                        let register = reader.uleb128()?;
+                        let other = reader.uleb128()? as u16;
+                        set(row, register, Rule::Register(other));
+                        None
+                    }
+                    0x0a => {
+                        // DW_CFA_remember_state
+                        if depth == MAX_REMEMBERED {
+                            return None;
+                        }
+                        remembered[depth] = *row;
+                        depth += 1;
+                        None
+                    }
+                    0x0b => {
+                        // DW_CFA_restore_state keeps the current CFA
+                        if depth == 0 {
+                            return None;
+                        }
+                        depth -= 1;
+                        row.rules = remembered[depth].rules;
+                        None
+                    }
+                    0x0c => {
+                        row.cfa_register = reader.uleb128()? as u16;
+                        row.cfa_offset = reader.uleb128()? as i64;
+                        None
+                    }
+                    0x0d => {
+                        row.cfa_register = reader.uleb128()? as u16;
+                        None
+                    }
+                    0x0e => {
+                        row.cfa_offset = reader.uleb128()? as i64;
+                        None
+                    }
+                    0x0f => {
+                        // DW_CFA_def_cfa_expression: not evaluated
+                        let length = reader.uleb128()? as usize;
+                        reader.bytes(length)?;
+                        row.cfa_register = u16::MAX;
+                        None
+                    }
+                    0x10 | 0x16 => {
+                        // DW_CFA_expression, DW_CFA_val_expression
+                        let register = reader.uleb128()?;
+                        let length = reader.uleb128()? as usize;
+                        reader.bytes(length)?;
+                        set(row, register, Rule::Unsupported);
+                        None
+                    }
+                    0x11 => {
+                        let register = reader.uleb128()?;
+                        let offset = reader.sleb128()? * cie.data_align;
+                        set(row, register, Rule::Offset(offset));
+                        None
+                    }
+                    0x12 => {
+                        row.cfa_register = reader.uleb128()? as u16;
+                        row.cfa_offset = reader.sleb128()? * cie.data_align;
+                        None
+                    }
+                    0x13 => {
+                        row.cfa_offset = reader.sleb128()? * cie.data_align;
+                        None
+                    }
+                    0x14 => {
+                        let register = reader.uleb128()?;
+                        let offset = reader.uleb128()? as i64 * cie.data_align;
+                        set(row, register, Rule::ValOffset(offset));
+                        None
+                    }
+                    0x15 => {
+                        let register = reader.uleb128()?;
+                        let offset = reader.sleb128()? * cie.data_align;
+                        set(row, register, Rule::ValOffset(offset));
+                        None
+                    }
+                    0x2e => {
+                        // DW_CFA_GNU_args_size
+                        reader.uleb128()?;
+                        None
+                    }
+                    0x2f => {
+                        // DW_CFA_GNU_negative_offset_extended
+                        let register = reader.uleb128()?;
+                        let offset = -(reader.uleb128()? as i64) * cie.data_align;
+                        set(row, register, Rule::Offset(offset));
+                        None
+                    }
+                    _ => return None,
+                },
+            };
+
+            if let Some(delta) = advance {
+                let next = location.wrapping_add(delta * cie.code_align);
+                if next > pc {
+                    return Some(());
+                }
+                *location = next;
+            }
+        }
+
+        Some(())
+    }
+}
@@ -1,39 +1,123 @@
 // Watchdog Timer and Hang Detection
 // Detects soft/hard lockups, RCU stalls, and system hangs
+//
+// Every CPU's timer tick is its heartbeat, and the scheduler, the idle loop
+// and the shell's polling loop touch the watchdog. A soft lockup is a CPU
+// that keeps taking ticks in kernel code without touching it; the tick that
+// notices reports the code it interrupted. A hard lockup is a CPU that
+// neither ticks nor touches, usually spinning with interrupts off. PMU
+// counter 1 raises an NMI on each CPU about once a second to catch that;
+// without a PMU each CPU's tick watches the next CPU and sends it an NMI
+// when it goes quiet. Either way the stuck CPU prints its own backtrace.
 
-use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use spin::Mutex;
-use lazy_static::lazy_static;
+use alloc::format;
 use alloc::vec::Vec;
 use alloc::string::String;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::unwind::{self, Frame};
+use crate::perf::{self, PerfEvent};
+use crate::smp::MAX_CPUS;
+
+/// PMU counter driving the hard lockup NMI; counter 0 belongs to the sampler
+pub const WATCHDOG_COUNTER: usize = 1;
+
+// Local APIC registers for the buddy NMI
+const APIC_BASE_ADDR: u64 = 0xFEE00000;
+const APIC_ID: u64 = 0x20;
+const APIC_ICR_LOW: u64 = 0x300;
+const APIC_ICR_HIGH: u64 = 0x310;
+const ICR_DELIVERY_NMI: u32 = 4 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+// Used until init() calibrates the TSC
+const DEFAULT_CYCLES_PER_MS: u64 = 2_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HardLockupMode {
+    Off,
+    Pmu,
+    Buddy,
+}
+
+impl HardLockupMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => HardLockupMode::Pmu,
+            2 => HardLockupMode::Buddy,
+            _ => HardLockupMode::Off,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HardLockupMode::Off => "off",
+            HardLockupMode::Pmu => "PMU NMI",
+            HardLockupMode::Buddy => "buddy CPU NMI",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lockup {
+    Soft,
+    Hard,
+}
+
+impl Lockup {
+    fn name(&self) -> &'static str {
+        match self {
+            Lockup::Soft => "soft lockup",
+            Lockup::Hard => "hard lockup",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchdogStatus {
+    pub enabled: bool,
+    pub soft_threshold_secs: u64,
+    pub hard_threshold_secs: u64,
+    pub hard_mode: HardLockupMode,
+    pub panic_on_lockup: bool,
+    pub dump_on_lockup: bool,
+    pub soft_lockups: u64,
+    pub hard_lockups: u64,
+}
 
 pub struct WatchdogSystem {
     enabled: AtomicBool,
-    nmi_watchdog: NmiWatchdog,
+    panic_on_lockup: AtomicBool,
+    dump_on_lockup: AtomicBool,
+    cycles_per_ms: AtomicU64,
     soft_lockup_detector: SoftLockupDetector,
     hard_lockup_detector: HardLockupDetector,
     rcu_stall_detector: RcuStallDetector,
     hung_task_detector: HungTaskDetector,
 }
 
-struct NmiWatchdog {
-    enabled: AtomicBool,
-    threshold_ms: AtomicU64,
-    last_touch: [AtomicU64; 256],  // Per-CPU timestamps
-    lockup_count: AtomicU64,
-}
-
 struct SoftLockupDetector {
     enabled: AtomicBool,
     threshold_ms: AtomicU64,
-    watchdog_touch: [AtomicU64; 256],  // Per-CPU touch timestamps
+    watchdog_touch: [AtomicU64; MAX_CPUS],  // Per-CPU touch timestamps
+    last_report: [AtomicU64; MAX_CPUS],
     detection_count: AtomicU64,
 }
 
 struct HardLockupDetector {
-    enabled: AtomicBool,
+    mode: AtomicU8,
     threshold_ms: AtomicU64,
-    nmi_count: [AtomicU64; 256],  // Per-CPU NMI counts
+    progress: [AtomicU64; MAX_CPUS],       // Per-CPU ticks and touches
+    seen: [AtomicU64; MAX_CPUS],           // Progress at the last check
+    seen_at: [AtomicU64; MAX_CPUS],        // When progress last moved
+    reported: [AtomicBool; MAX_CPUS],
+    armed: [AtomicBool; MAX_CPUS],         // Watchdog counter programmed
+    nmi_requested: [AtomicBool; MAX_CPUS], // Buddy NMI on its way
+    apic_ids: [AtomicU32; MAX_CPUS],       // APIC ID + 1, 0 if unknown
     detection_count: AtomicU64,
 }
 
@@ -41,7 +125,7 @@ struct RcuStallDetector {
     enabled: AtomicBool,
     grace_period_start: AtomicU64,
     stall_threshold_ms: AtomicU64,
-    quiescent_states: [AtomicU64; 256],  // Per-CPU QS counts
+    quiescent_states: [AtomicU64; MAX_CPUS],  // Per-CPU QS counts
     stall_count: AtomicU64,
 }
 
@@ -61,417 +145,450 @@ struct MonitoredTask {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum TaskState {
+pub enum TaskState {
     Running,
     Sleeping,
     Uninterruptible,
     Zombie,
 }
 
-lazy_static! {
-    pub static ref WATCHDOG: WatchdogSystem = WatchdogSystem::new();
-}
+// A plain static so the NMI path never runs a lazy initializer
+pub static WATCHDOG: WatchdogSystem = WatchdogSystem::new();
 
 impl WatchdogSystem {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            enabled: AtomicBool::new(true),
-            nmi_watchdog: NmiWatchdog::new(),
+            enabled: AtomicBool::new(false),
+            panic_on_lockup: AtomicBool::new(false),
+            dump_on_lockup: AtomicBool::new(false),
+            cycles_per_ms: AtomicU64::new(DEFAULT_CYCLES_PER_MS),
             soft_lockup_detector: SoftLockupDetector::new(),
             hard_lockup_detector: HardLockupDetector::new(),
             rcu_stall_detector: RcuStallDetector::new(),
             hung_task_detector: HungTaskDetector::new(),
         }
     }
-    
+
     pub fn init(&self) {
-        // Initialize hardware watchdog if available
-        self.init_hardware_watchdog();
-        
-        // Set up NMI watchdog
-        self.nmi_watchdog.init();
-        
-        // Start soft lockup detection
+        // Calibrating goes through the PIT, so only do it once
+        let tsc_hz = crate::timer::get_tsc_frequency();
+        if tsc_hz >= 1000 {
+            self.cycles_per_ms.store(tsc_hz / 1000, Ordering::Relaxed);
+        }
+
         self.soft_lockup_detector.init();
-        
+        self.hard_lockup_detector.init(self.cycles_per_ms());
+
         self.enabled.store(true, Ordering::SeqCst);
         crate::serial_println!("[WATCHDOG] System watchdog initialized");
     }
-    
-    fn init_hardware_watchdog(&self) {
-        // Initialize hardware watchdog timer if available
-        // This would configure:
-        // - Intel TCO watchdog
-        // - AMD watchdog
-        // - HPET watchdog
-        
-        crate::serial_println!("[WATCHDOG] Hardware watchdog configured");
-    }
-    
+
     pub fn touch(&self) {
-        // Called periodically to indicate system is alive
-        let cpu_id = self.get_cpu_id();
+        // Called periodically to indicate this CPU is alive
+        let cpu_id = current_cpu();
         let timestamp = self.get_timestamp();
-        
-        // Update soft lockup detector
+
         self.soft_lockup_detector.touch(cpu_id, timestamp);
-        
-        // Update NMI watchdog
-        self.nmi_watchdog.touch(cpu_id, timestamp);
+        self.hard_lockup_detector.progress[cpu_id].fetch_add(1, Ordering::Relaxed);
+
+        if self.enabled.load(Ordering::Relaxed) {
+            self.hard_lockup_detector.sync(cpu_id, self.cycles_per_ms());
+        }
     }
-    
+
+    pub fn heartbeat(&self, frame: &InterruptStackFrame, frame_pointer: u64) {
+        let cpu_id = current_cpu();
+        let hard = &self.hard_lockup_detector;
+        hard.progress[cpu_id].fetch_add(1, Ordering::Relaxed);
+
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let cycles_per_ms = self.cycles_per_ms();
+        hard.sync(cpu_id, cycles_per_ms);
+
+        let timestamp = self.get_timestamp();
+        // Code interrupted in user mode is preemptible, not locked up
+        if frame.code_segment & 3 == 3 {
+            self.soft_lockup_detector.touch(cpu_id, timestamp);
+        } else if let Some(duration_ms) = self.soft_lockup_detector.check(cpu_id, timestamp, cycles_per_ms) {
+            self.report_lockup(Lockup::Soft, cpu_id, duration_ms, frame, frame_pointer);
+        }
+
+        if hard.mode() == HardLockupMode::Buddy {
+            hard.check_buddy(cpu_id, timestamp, cycles_per_ms);
+        }
+    }
+
     pub fn check(&self) {
         if !self.enabled.load(Ordering::Relaxed) {
             return;
         }
-        
+
         let timestamp = self.get_timestamp();
-        
-        // Check for soft lockups
-        self.soft_lockup_detector.check(timestamp);
-        
-        // Check for hard lockups
-        self.hard_lockup_detector.check(timestamp);
-        
-        // Check for RCU stalls
+
+        // Lockups are checked from the tick and the NMI; these need a caller
         self.rcu_stall_detector.check(timestamp);
-        
-        // Check for hung tasks
         self.hung_task_detector.check(timestamp);
     }
-    
-    pub fn handle_nmi(&self) {
-        // Called from NMI handler
-        let cpu_id = self.get_cpu_id();
-        
-        // Increment NMI count for hard lockup detection
-        self.hard_lockup_detector.nmi_count[cpu_id as usize]
-            .fetch_add(1, Ordering::Relaxed);
-        
-        // Check if this CPU is locked up
-        if self.nmi_watchdog.is_locked_up(cpu_id) {
-            self.handle_hard_lockup(cpu_id);
-        }
-    }
-    
-    fn handle_soft_lockup(&self, cpu_id: u32, duration_ms: u64) {
-        let count = self.soft_lockup_detector.detection_count
-            .fetch_add(1, Ordering::SeqCst);
-        
+
+    pub fn counter_overflow(&self, frame: &InterruptStackFrame, frame_pointer: u64) {
+        let cpu_id = current_cpu();
+        let hard = &self.hard_lockup_detector;
+
+        if !self.enabled.load(Ordering::Relaxed) || hard.mode() != HardLockupMode::Pmu {
+            perf::disarm_counter(WATCHDOG_COUNTER);
+            hard.armed[cpu_id].store(false, Ordering::Relaxed);
+            return;
+        }
+
+        let cycles_per_ms = self.cycles_per_ms();
+        perf::reload_sampling_counter(WATCHDOG_COUNTER, nmi_period(cycles_per_ms));
+
+        if let Some(duration_ms) = hard.stalled(cpu_id, self.get_timestamp(), cycles_per_ms) {
+            if !hard.reported[cpu_id].swap(true, Ordering::Relaxed) {
+                self.report_lockup(Lockup::Hard, cpu_id, duration_ms, frame, frame_pointer);
+            }
+        }
+    }
+
+    pub fn handle_nmi(&self, frame: &InterruptStackFrame, frame_pointer: u64) -> bool {
+        // Only NMIs a buddy CPU sent are ours; PMU overflows come via perf
+        let cpu_id = current_cpu();
+        let hard = &self.hard_lockup_detector;
+        if !hard.nmi_requested[cpu_id].swap(false, Ordering::Relaxed) {
+            return false;
+        }
+
+        let stalled = self.get_timestamp().saturating_sub(hard.seen_at[cpu_id].load(Ordering::Relaxed));
+        self.report_lockup(Lockup::Hard, cpu_id, stalled / self.cycles_per_ms(), frame, frame_pointer);
+        true
+    }
+
+    fn report_lockup(&self, lockup: Lockup, cpu_id: usize, duration_ms: u64, frame: &InterruptStackFrame, frame_pointer: u64) {
+        match lockup {
+            Lockup::Soft => self.soft_lockup_detector.detection_count.fetch_add(1, Ordering::SeqCst),
+            Lockup::Hard => self.hard_lockup_detector.detection_count.fetch_add(1, Ordering::SeqCst),
+        };
+
+        // Hard lockups are reported from an NMI, which may have stopped this
+        // CPU inside the console or the allocator
+        let in_nmi = lockup == Lockup::Hard;
+        if in_nmi {
+            crate::klog::unlock_for_nmi();
+        }
+        let can_allocate = !in_nmi || !crate::allocator::is_busy();
+
         crate::serial_println!("\n==================================");
-        crate::serial_println!("BUG: soft lockup - CPU#{} stuck for {}ms!",
-            cpu_id, duration_ms);
+        crate::serial_println!("BUG: {} - CPU#{} stuck for {}s!",
+            lockup.name(), cpu_id, duration_ms / 1000);
         crate::serial_println!("==================================");
-        
-        // Print CPU state
-        self.print_cpu_state(cpu_id);
-        
-        // Print stack trace
-        if let Some(trace) = super::generate_stack_trace() {
-            crate::serial_println!("\nStack trace:");
-            for frame in trace {
-                crate::serial_println!("  {}", frame);
+
+        self.print_task(cpu_id, in_nmi, can_allocate);
+
+        let rip = frame.instruction_pointer.as_u64();
+        let rsp = frame.stack_pointer.as_u64();
+        crate::serial_println!("RIP: {:#018x} RSP: {:#018x} RFLAGS: {:#x}",
+            rip, rsp, frame.cpu_flags);
+
+        crate::serial_println!("Call Trace:");
+        let start = Frame::new(rip, rsp, frame_pointer);
+        if can_allocate {
+            unwind::print_backtrace(&unwind::backtrace(start, unwind::MAX_FRAMES));
+        } else {
+            let mut chain = [0u64; 32];
+            let depth = unwind::frame_pointer_chain(start, &mut chain);
+            crate::serial_println!("  [<{:#018x}>]", rip);
+            for address in &chain[..depth] {
+                crate::serial_println!("  [<{:#018x}>]", address);
             }
+            crate::serial_println!("  (allocator busy, addresses not symbolized)");
         }
-        
-        // Enter debugger if available
-        if super::DEBUG_STATE.kdb_enabled.load(Ordering::Relaxed) {
-            super::kdb::enter_debugger(&format!("Soft lockup on CPU{}", cpu_id));
+
+        if self.dump_on_lockup.load(Ordering::Relaxed) {
+            if can_allocate {
+                let message = format!("{} on CPU{}", lockup.name(), cpu_id);
+                if let Err(e) = super::kdump::CRASH_DUMP.create_dump_with_message(message) {
+                    crate::serial_println!("[WATCHDOG] Crash dump failed: {}", e);
+                }
+            } else {
+                crate::serial_println!("[WATCHDOG] Crash dump skipped: allocator busy");
+            }
         }
-        
-        // Optionally panic if configured
-        if duration_ms > 60000 {  // > 60 seconds
-            panic!("Soft lockup detected for over 60 seconds");
+
+        if self.panic_on_lockup.load(Ordering::Relaxed) {
+            panic!("{} on CPU{}", lockup.name(), cpu_id);
         }
     }
-    
-    fn handle_hard_lockup(&self, cpu_id: u32) {
-        let count = self.hard_lockup_detector.detection_count
-            .fetch_add(1, Ordering::SeqCst);
-        
-        crate::serial_println!("\n==================================");
-        crate::serial_println!("BUG: hard lockup - CPU#{} stuck!", cpu_id);
-        crate::serial_println!("==================================");
-        
-        // Hard lockup is critical - system is unresponsive
-        // Try to gather as much info as possible
-        
-        // Trigger crash dump
-        if super::DEBUG_STATE.kdump_enabled.load(Ordering::Relaxed) {
-            let panic_info = core::panic::PanicInfo::internal_constructor(
-                Some(&format!("Hard lockup on CPU{}", cpu_id)),
-                core::panic::Location::caller(),
-                false,
-            );
-            super::kdump::create_dump(&panic_info);
-        }
-        
-        // Force reboot after delay
-        self.emergency_reboot();
-    }
-    
+
+    fn print_task(&self, cpu_id: usize, in_nmi: bool, can_allocate: bool) {
+        // The scheduler's lazy static may need the heap on first use, and
+        // the process manager lock is validated, which an NMI must not do
+        let thread = if can_allocate {
+            crate::process::smp_scheduler::SMP_SCHEDULER.current_thread(cpu_id as u32)
+        } else {
+            0
+        };
+        let pid = if in_nmi {
+            None
+        } else {
+            crate::process::PROCESS_MANAGER.try_lock().and_then(|manager| manager.current_process)
+        };
+
+        match pid {
+            Some(pid) => crate::serial_println!("Task: pid {} thread {}", pid.0, thread),
+            None => crate::serial_println!("Task: thread {}", thread),
+        }
+    }
+
     fn handle_rcu_stall(&self, duration_ms: u64) {
-        let count = self.rcu_stall_detector.stall_count
-            .fetch_add(1, Ordering::SeqCst);
-        
+        self.rcu_stall_detector.stall_count.fetch_add(1, Ordering::SeqCst);
+
         crate::serial_println!("\n==================================");
         crate::serial_println!("INFO: rcu_sched detected stall ({}ms)", duration_ms);
         crate::serial_println!("==================================");
-        
+
         // Print CPUs that haven't reported quiescent state
         crate::serial_println!("CPUs stalling:");
-        for cpu in 0..self.get_cpu_count() {
-            let last_qs = self.rcu_stall_detector.quiescent_states[cpu as usize]
-                .load(Ordering::Relaxed);
-            if last_qs < self.rcu_stall_detector.grace_period_start.load(Ordering::Relaxed) {
+        let gp_start = self.rcu_stall_detector.grace_period_start.load(Ordering::Relaxed);
+        for cpu in 0..MAX_CPUS {
+            // CPUs that never ticked are not online
+            if self.hard_lockup_detector.progress[cpu].load(Ordering::Relaxed) == 0 {
+                continue;
+            }
+            let last_qs = self.rcu_stall_detector.quiescent_states[cpu].load(Ordering::Relaxed);
+            if last_qs < gp_start {
                 crate::serial_println!("  CPU{}: no QS", cpu);
             }
         }
-        
-        // Print stack traces for stalled CPUs
-        self.print_all_cpu_stacks();
     }
-    
+
     fn handle_hung_task(&self, task: &MonitoredTask, duration_ms: u64) {
-        let count = self.hung_task_detector.hung_count
-            .fetch_add(1, Ordering::SeqCst);
-        
+        self.hung_task_detector.hung_count.fetch_add(1, Ordering::SeqCst);
+
         crate::serial_println!("\n==================================");
         crate::serial_println!("INFO: task {}:{} blocked for {}ms",
             task.name, task.pid, duration_ms);
         crate::serial_println!("==================================");
-        
-        // Show task state
+
         crate::serial_println!("Task state: {:?}", task.state);
-        
-        // Would show task's kernel stack
-        crate::serial_println!("Kernel stack:");
-        crate::serial_println!("  [stack trace would go here]");
-    }
-    
-    fn print_cpu_state(&self, cpu_id: u32) {
-        crate::serial_println!("\nCPU {} state:", cpu_id);
-        
-        // Print interrupt state
-        let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
-        crate::serial_println!("  Interrupts: {}", 
-            if interrupts_enabled { "enabled" } else { "disabled" });
-        
-        // Print current task info
-        crate::serial_println!("  Current task: kernel");
-        
-        // Print preemption state
-        crate::serial_println!("  Preemption: disabled");  // Would check actual state
-    }
-    
-    fn print_all_cpu_stacks(&self) {
-        crate::serial_println!("\nBacktraces for all CPUs:");
-        
-        for cpu in 0..self.get_cpu_count() {
-            crate::serial_println!("\nCPU {}:", cpu);
-            // Would send IPI to get stack trace from each CPU
-            if cpu == self.get_cpu_id() {
-                if let Some(trace) = super::generate_stack_trace() {
-                    for frame in trace {
-                        crate::serial_println!("  {}", frame);
-                    }
-                }
-            }
-        }
     }
-    
-    fn emergency_reboot(&self) {
-        crate::serial_println!("\n!!! EMERGENCY REBOOT IN 5 SECONDS !!!");
-        
-        // Give time to flush logs
-        for i in (1..=5).rev() {
-            crate::serial_println!("Rebooting in {}...", i);
-            self.delay_ms(1000);
-        }
-        
-        // Force reboot
-        unsafe {
-            // Triple fault
-            core::arch::asm!("xor %eax, %eax");
-            core::arch::asm!("mov %eax, %cr3");
+
+    pub fn status(&self) -> WatchdogStatus {
+        let soft = &self.soft_lockup_detector;
+        let hard = &self.hard_lockup_detector;
+        WatchdogStatus {
+            enabled: self.enabled.load(Ordering::Relaxed),
+            soft_threshold_secs: if soft.enabled.load(Ordering::Relaxed) {
+                soft.threshold_ms.load(Ordering::Relaxed) / 1000
+            } else {
+                0
+            },
+            hard_threshold_secs: hard.threshold_ms.load(Ordering::Relaxed) / 1000,
+            hard_mode: hard.mode(),
+            panic_on_lockup: self.panic_on_lockup.load(Ordering::Relaxed),
+            dump_on_lockup: self.dump_on_lockup.load(Ordering::Relaxed),
+            soft_lockups: soft.detection_count.load(Ordering::Relaxed),
+            hard_lockups: hard.detection_count.load(Ordering::Relaxed),
         }
     }
-    
+
+    fn cycles_per_ms(&self) -> u64 {
+        self.cycles_per_ms.load(Ordering::Relaxed)
+    }
+
     fn get_timestamp(&self) -> u64 {
         unsafe { core::arch::x86_64::_rdtsc() }
     }
-    
-    fn get_cpu_id(&self) -> u32 {
-        0  // Would get actual CPU ID
-    }
-    
-    fn get_cpu_count(&self) -> u32 {
-        1  // Would get actual CPU count
-    }
-    
-    fn delay_ms(&self, ms: u64) {
-        // Simple delay loop
-        let start = self.get_timestamp();
-        let cycles_per_ms = 2_000_000;  // Approximate
-        let target = start + (ms * cycles_per_ms);
-        
-        while self.get_timestamp() < target {
-            core::hint::spin_loop();
-        }
-    }
 }
 
-impl NmiWatchdog {
-    fn new() -> Self {
+impl SoftLockupDetector {
+    const fn new() -> Self {
         Self {
             enabled: AtomicBool::new(true),
-            threshold_ms: AtomicU64::new(10000),  // 10 seconds
-            last_touch: Default::default(),
-            lockup_count: AtomicU64::new(0),
+            threshold_ms: AtomicU64::new(20000),  // 20 seconds
+            watchdog_touch: [const { AtomicU64::new(0) }; MAX_CPUS],
+            last_report: [const { AtomicU64::new(0) }; MAX_CPUS],
+            detection_count: AtomicU64::new(0),
         }
     }
-    
+
     fn init(&self) {
-        // Configure NMI watchdog using performance counters
-        // Would set up:
-        // - Local APIC timer for NMI
-        // - Performance counter overflow to trigger NMI
-        
-        self.enabled.store(true, Ordering::SeqCst);
-        crate::serial_println!("[WATCHDOG] NMI watchdog enabled");
+        // Initialize touch timestamps
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
+        for touch in &self.watchdog_touch {
+            touch.store(now, Ordering::Relaxed);
+        }
+
+        crate::serial_println!("[WATCHDOG] Soft lockup detector enabled ({}s)",
+            self.threshold_ms.load(Ordering::Relaxed) / 1000);
     }
-    
-    fn touch(&self, cpu_id: u32, timestamp: u64) {
-        self.last_touch[cpu_id as usize].store(timestamp, Ordering::Relaxed);
+
+    fn touch(&self, cpu_id: usize, timestamp: u64) {
+        self.watchdog_touch[cpu_id].store(timestamp, Ordering::Relaxed);
     }
-    
-    fn is_locked_up(&self, cpu_id: u32) -> bool {
+
+    /// How long `cpu_id` has been stuck, once per threshold period
+    fn check(&self, cpu_id: usize, timestamp: u64, cycles_per_ms: u64) -> Option<u64> {
         if !self.enabled.load(Ordering::Relaxed) {
-            return false;
+            return None;
         }
-        
-        let last = self.last_touch[cpu_id as usize].load(Ordering::Relaxed);
-        let now = unsafe { core::arch::x86_64::_rdtsc() };
-        
-        let cycles_per_ms = 2_000_000;  // Approximate
+
         let threshold = self.threshold_ms.load(Ordering::Relaxed) * cycles_per_ms;
-        
-        (now - last) > threshold
+        let last_touch = self.watchdog_touch[cpu_id].load(Ordering::Relaxed);
+        let duration = timestamp.saturating_sub(last_touch);
+        let since_report = timestamp.saturating_sub(self.last_report[cpu_id].load(Ordering::Relaxed));
+
+        if duration < threshold || since_report < threshold {
+            return None;
+        }
+        self.last_report[cpu_id].store(timestamp, Ordering::Relaxed);
+        Some(duration / cycles_per_ms)
     }
 }
 
-impl SoftLockupDetector {
-    fn new() -> Self {
+impl HardLockupDetector {
+    const fn new() -> Self {
         Self {
-            enabled: AtomicBool::new(true),
-            threshold_ms: AtomicU64::new(20000),  // 20 seconds
-            watchdog_touch: Default::default(),
+            mode: AtomicU8::new(HardLockupMode::Off as u8),
+            threshold_ms: AtomicU64::new(10000),  // 10 seconds
+            progress: [const { AtomicU64::new(0) }; MAX_CPUS],
+            seen: [const { AtomicU64::new(0) }; MAX_CPUS],
+            seen_at: [const { AtomicU64::new(0) }; MAX_CPUS],
+            reported: [const { AtomicBool::new(false) }; MAX_CPUS],
+            armed: [const { AtomicBool::new(false) }; MAX_CPUS],
+            nmi_requested: [const { AtomicBool::new(false) }; MAX_CPUS],
+            apic_ids: [const { AtomicU32::new(0) }; MAX_CPUS],
             detection_count: AtomicU64::new(0),
         }
     }
-    
-    fn init(&self) {
-        self.enabled.store(true, Ordering::SeqCst);
-        
-        // Initialize touch timestamps
-        let now = unsafe { core::arch::x86_64::_rdtsc() };
-        for i in 0..256 {
-            self.watchdog_touch[i].store(now, Ordering::Relaxed);
+
+    fn init(&self, cycles_per_ms: u64) {
+        let mode = Self::best_mode();
+        self.mode.store(mode as u8, Ordering::SeqCst);
+        // Other CPUs program their counter on their first tick or touch
+        self.sync(current_cpu(), cycles_per_ms);
+
+        crate::serial_println!("[WATCHDOG] Hard lockup detector enabled ({}s, {})",
+            self.threshold_ms.load(Ordering::Relaxed) / 1000, mode.name());
+    }
+
+    fn best_mode() -> HardLockupMode {
+        if perf::sampling_counters() > WATCHDOG_COUNTER {
+            HardLockupMode::Pmu
+        } else {
+            HardLockupMode::Buddy
         }
-        
-        crate::serial_println!("[WATCHDOG] Soft lockup detector enabled");
     }
-    
-    fn touch(&self, cpu_id: u32, timestamp: u64) {
-        self.watchdog_touch[cpu_id as usize].store(timestamp, Ordering::Relaxed);
+
+    fn mode(&self) -> HardLockupMode {
+        HardLockupMode::from_u8(self.mode.load(Ordering::Relaxed))
     }
-    
-    fn check(&self, timestamp: u64) {
-        if !self.enabled.load(Ordering::Relaxed) {
+
+    /// Bring this CPU's watchdog counter in line with the current mode
+    fn sync(&self, cpu_id: usize, cycles_per_ms: u64) {
+        let mode = self.mode();
+        if mode == HardLockupMode::Buddy && self.apic_ids[cpu_id].load(Ordering::Relaxed) == 0 {
+            self.apic_ids[cpu_id].store(local_apic_id() + 1, Ordering::Relaxed);
+        }
+
+        let want = mode == HardLockupMode::Pmu;
+        if self.armed[cpu_id].load(Ordering::Relaxed) == want {
             return;
         }
-        
-        let cycles_per_ms = 2_000_000;  // Approximate
-        let threshold = self.threshold_ms.load(Ordering::Relaxed) * cycles_per_ms;
-        
-        for cpu in 0..WATCHDOG.get_cpu_count() {
-            let last_touch = self.watchdog_touch[cpu as usize].load(Ordering::Relaxed);
-            let duration = timestamp - last_touch;
-            
-            if duration > threshold {
-                let duration_ms = duration / cycles_per_ms;
-                WATCHDOG.handle_soft_lockup(cpu, duration_ms);
-            }
+        if want {
+            perf::arm_sampling_counter(WATCHDOG_COUNTER, PerfEvent::CpuCycles, nmi_period(cycles_per_ms));
+        } else {
+            perf::disarm_counter(WATCHDOG_COUNTER);
         }
+        self.armed[cpu_id].store(want, Ordering::Relaxed);
     }
-}
 
-impl HardLockupDetector {
-    fn new() -> Self {
-        Self {
-            enabled: AtomicBool::new(true),
-            threshold_ms: AtomicU64::new(10000),  // 10 seconds
-            nmi_count: Default::default(),
-            detection_count: AtomicU64::new(0),
+    /// How long `cpu_id` has made no progress, if past the threshold
+    fn stalled(&self, cpu_id: usize, timestamp: u64, cycles_per_ms: u64) -> Option<u64> {
+        let progress = self.progress[cpu_id].load(Ordering::Relaxed);
+        let seen_at = self.seen_at[cpu_id].load(Ordering::Relaxed);
+
+        if seen_at == 0 || self.seen[cpu_id].swap(progress, Ordering::Relaxed) != progress {
+            self.seen_at[cpu_id].store(timestamp, Ordering::Relaxed);
+            self.reported[cpu_id].store(false, Ordering::Relaxed);
+            return None;
         }
+
+        let stalled_ms = timestamp.saturating_sub(seen_at) / cycles_per_ms;
+        (stalled_ms >= self.threshold_ms.load(Ordering::Relaxed)).then_some(stalled_ms)
     }
-    
-    fn check(&self, _timestamp: u64) {
-        // Check if NMI counts are incrementing
-        // If not, CPU is hard locked
-        // This is typically done from NMI context
+
+    /// Watch the next CPU that has ticked and NMI it once it stops
+    fn check_buddy(&self, cpu_id: usize, timestamp: u64, cycles_per_ms: u64) {
+        let buddy = (1..MAX_CPUS)
+            .map(|offset| (cpu_id + offset) % MAX_CPUS)
+            .find(|&cpu| self.progress[cpu].load(Ordering::Relaxed) != 0);
+        let Some(buddy) = buddy else {
+            return;
+        };
+
+        if self.stalled(buddy, timestamp, cycles_per_ms).is_none() {
+            return;
+        }
+        let apic_id = self.apic_ids[buddy].load(Ordering::Relaxed);
+        if apic_id != 0 && !self.reported[buddy].swap(true, Ordering::Relaxed) {
+            self.nmi_requested[buddy].store(true, Ordering::Relaxed);
+            send_nmi(apic_id - 1);
+        }
     }
 }
 
 impl RcuStallDetector {
-    fn new() -> Self {
+    const fn new() -> Self {
         Self {
             enabled: AtomicBool::new(true),
             grace_period_start: AtomicU64::new(0),
             stall_threshold_ms: AtomicU64::new(21000),  // 21 seconds
-            quiescent_states: Default::default(),
+            quiescent_states: [const { AtomicU64::new(0) }; MAX_CPUS],
             stall_count: AtomicU64::new(0),
         }
     }
-    
+
     fn check(&self, timestamp: u64) {
         if !self.enabled.load(Ordering::Relaxed) {
             return;
         }
-        
+
         let gp_start = self.grace_period_start.load(Ordering::Relaxed);
         if gp_start == 0 {
             return;  // No grace period in progress
         }
-        
-        let cycles_per_ms = 2_000_000;
+
+        let cycles_per_ms = WATCHDOG.cycles_per_ms();
         let threshold = self.stall_threshold_ms.load(Ordering::Relaxed) * cycles_per_ms;
-        let duration = timestamp - gp_start;
-        
+        let duration = timestamp.saturating_sub(gp_start);
+
         if duration > threshold {
             let duration_ms = duration / cycles_per_ms;
             WATCHDOG.handle_rcu_stall(duration_ms);
         }
     }
-    
+
     pub fn report_qs(&self, cpu_id: u32) {
         // Report quiescent state for CPU
         let timestamp = unsafe { core::arch::x86_64::_rdtsc() };
-        self.quiescent_states[cpu_id as usize].store(timestamp, Ordering::Relaxed);
+        self.quiescent_states[cpu_id as usize % MAX_CPUS].store(timestamp, Ordering::Relaxed);
     }
-    
+
     pub fn start_grace_period(&self) {
         let timestamp = unsafe { core::arch::x86_64::_rdtsc() };
         self.grace_period_start.store(timestamp, Ordering::Relaxed);
     }
-    
+
     pub fn end_grace_period(&self) {
         self.grace_period_start.store(0, Ordering::Relaxed);
     }
 }
 
 impl HungTaskDetector {
-    fn new() -> Self {
+    const fn new() -> Self {
         Self {
             enabled: AtomicBool::new(true),
             threshold_ms: AtomicU64::new(120000),  // 2 minutes
@@ -479,19 +596,19 @@ impl HungTaskDetector {
             hung_count: AtomicU64::new(0),
         }
     }
-    
+
     fn check(&self, timestamp: u64) {
         if !self.enabled.load(Ordering::Relaxed) {
             return;
         }
-        
-        let cycles_per_ms = 2_000_000;
+
+        let cycles_per_ms = WATCHDOG.cycles_per_ms();
         let threshold = self.threshold_ms.load(Ordering::Relaxed) * cycles_per_ms;
-        
+
         let tasks = self.tasks.lock();
         for task in tasks.iter() {
             if task.state == TaskState::Uninterruptible {
-                let duration = timestamp - task.last_progress;
+                let duration = timestamp.saturating_sub(task.last_progress);
                 if duration > threshold {
                     let duration_ms = duration / cycles_per_ms;
                     WATCHDOG.handle_hung_task(task, duration_ms);
@@ -499,19 +616,20 @@ impl HungTaskDetector {
             }
         }
     }
-    
+
     pub fn register_task(&self, pid: u32, name: String) {
+        let now = unsafe { core::arch::x86_64::_rdtsc() };
         let task = MonitoredTask {
             pid,
             name,
-            start_time: unsafe { core::arch::x86_64::_rdtsc() },
+            start_time: now,
             state: TaskState::Running,
-            last_progress: unsafe { core::arch::x86_64::_rdtsc() },
+            last_progress: now,
         };
-        
+
         self.tasks.lock().push(task);
     }
-    
+
     pub fn update_task_state(&self, pid: u32, state: TaskState) {
         let mut tasks = self.tasks.lock();
         if let Some(task) = tasks.iter_mut().find(|t| t.pid == pid) {
@@ -523,6 +641,46 @@ impl HungTaskDetector {
     }
 }
 
+fn current_cpu() -> usize {
+    crate::smp::current_cpu_id() as usize % MAX_CPUS
+}
+
+// Roughly one NMI per second of unhalted cycles
+fn nmi_period(cycles_per_ms: u64) -> u64 {
+    (cycles_per_ms * 1000).min(perf::MAX_SAMPLE_PERIOD)
+}
+
+fn local_apic_id() -> u32 {
+    unsafe { core::ptr::read_volatile((APIC_BASE_ADDR + APIC_ID) as *const u32) >> 24 }
+}
+
+fn send_nmi(apic_id: u32) {
+    unsafe {
+        let icr_high = (APIC_BASE_ADDR + APIC_ICR_HIGH) as *mut u32;
+        let icr_low = (APIC_BASE_ADDR + APIC_ICR_LOW) as *mut u32;
+        core::ptr::write_volatile(icr_high, apic_id << 24);
+        core::ptr::write_volatile(icr_low, ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT);
+
+        for _ in 0..100_000 {
+            if core::ptr::read_volatile(icr_low) & ICR_SEND_PENDING == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Spin until the threshold has passed, plus a few seconds for the report
+fn spin_past(threshold_ms: u64) {
+    let cycles_per_ms = WATCHDOG.cycles_per_ms();
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    let duration = (threshold_ms + 5000) * cycles_per_ms;
+
+    while unsafe { core::arch::x86_64::_rdtsc() }.saturating_sub(start) < duration {
+        core::hint::spin_loop();
+    }
+}
+
 // Public API
 pub fn init() {
     WATCHDOG.init();
@@ -532,34 +690,84 @@ pub fn touch() {
     WATCHDOG.touch();
 }
 
+pub fn heartbeat(frame: &InterruptStackFrame, frame_pointer: u64) {
+    WATCHDOG.heartbeat(frame, frame_pointer);
+}
+
 pub fn check() {
     WATCHDOG.check();
 }
 
-pub fn handle_nmi() {
-    WATCHDOG.handle_nmi();
+pub fn counter_overflow(frame: &InterruptStackFrame, frame_pointer: u64) {
+    WATCHDOG.counter_overflow(frame, frame_pointer);
+}
+
+pub fn handle_nmi(frame: &InterruptStackFrame, frame_pointer: u64) -> bool {
+    WATCHDOG.handle_nmi(frame, frame_pointer)
 }
 
 pub fn pet_watchdog() {
-    // Reset hardware watchdog timer
     WATCHDOG.touch();
 }
 
-pub fn trigger_test_lockup() {
-    crate::serial_println!("[WATCHDOG] Triggering test soft lockup...");
-    crate::serial_println!("[WATCHDOG] System will appear frozen for 30 seconds");
-    
-    // Disable interrupts and spin
-    x86_64::instructions::interrupts::disable();
-    
-    let start = unsafe { core::arch::x86_64::_rdtsc() };
-    let cycles_per_ms = 2_000_000;
-    let duration = 30000 * cycles_per_ms;  // 30 seconds
-    
-    while (unsafe { core::arch::x86_64::_rdtsc() } - start) < duration {
-        core::hint::spin_loop();
+pub fn status() -> WatchdogStatus {
+    WATCHDOG.status()
+}
+
+/// Soft lockup threshold in seconds; 0 turns the detector off
+pub fn set_soft_threshold(secs: u64) {
+    let soft = &WATCHDOG.soft_lockup_detector;
+    if secs > 0 {
+        soft.threshold_ms.store(secs * 1000, Ordering::Relaxed);
+    }
+    soft.enabled.store(secs > 0, Ordering::Relaxed);
+}
+
+/// Hard lockup threshold in seconds; 0 turns the detector off. Other CPUs
+/// pick up the change on their next tick or touch.
+pub fn set_hard_threshold(secs: u64) {
+    let hard = &WATCHDOG.hard_lockup_detector;
+    let mode = if secs > 0 {
+        hard.threshold_ms.store(secs * 1000, Ordering::Relaxed);
+        HardLockupDetector::best_mode()
+    } else {
+        HardLockupMode::Off
+    };
+    hard.mode.store(mode as u8, Ordering::SeqCst);
+    if WATCHDOG.enabled.load(Ordering::Relaxed) {
+        hard.sync(current_cpu(), WATCHDOG.cycles_per_ms());
     }
-    
+}
+
+pub fn set_panic_on_lockup(enabled: bool) {
+    WATCHDOG.panic_on_lockup.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_dump_on_lockup(enabled: bool) {
+    WATCHDOG.dump_on_lockup.store(enabled, Ordering::Relaxed);
+}
+
+/// Spin in the kernel with interrupts on, so only the tick sees this CPU
+pub fn trigger_test_lockup() {
+    let threshold_ms = WATCHDOG.soft_lockup_detector.threshold_ms.load(Ordering::Relaxed);
+    crate::serial_println!("[WATCHDOG] Triggering test soft lockup for {}s...", threshold_ms / 1000 + 5);
+
+    let were_enabled = x86_64::instructions::interrupts::are_enabled();
     x86_64::instructions::interrupts::enable();
+    spin_past(threshold_ms);
+    if !were_enabled {
+        x86_64::instructions::interrupts::disable();
+    }
+
     crate::serial_println!("[WATCHDOG] Test lockup complete");
-}
\ No newline at end of file
+}
+
+/// Spin with interrupts off, so only the NMI sees this CPU
+pub fn trigger_test_hard_lockup() {
+    let threshold_ms = WATCHDOG.hard_lockup_detector.threshold_ms.load(Ordering::Relaxed);
+    crate::serial_println!("[WATCHDOG] Triggering test hard lockup for {}s...", threshold_ms / 1000 + 5);
+
+    x86_64::instructions::interrupts::without_interrupts(|| spin_past(threshold_ms));
+
+    crate::serial_println!("[WATCHDOG] Test lockup complete");
+}
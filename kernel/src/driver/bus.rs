@@ -1,565 +1,153 @@
-//! Bus Infrastructure - Generic bus framework for device enumeration and management
+//! Bus enumeration: finding what is on each bus and adding it to the tree
+//!
+//! Every bus has a root device that its devices hang from: `platform`, and
+//! `pci0000:00` for the PCI host bridge. USB devices hang from their host
+//! controller's PCI function, and from the hub they are plugged into.
 
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::String,
-    sync::Arc,
-    vec::Vec,
-};
-use core::{
-    fmt,
-    sync::atomic::{AtomicU32, Ordering},
-};
-use spin::RwLock;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 
-use super::{
-    Device, DeviceClass, DeviceId, DeviceResource, Driver, DriverError, Result,
-    model::{DeviceBuilder, DeviceCapabilities, MemoryFlags, InterruptFlags},
-};
+use spin::Once;
 
-/// Bus type identifier
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum BusType {
-    Pci,
-    Usb,
-    I2c,
-    Spi,
-    Platform,
-    Virtual,
-    Isa,
-    Scsi,
-    Nvme,
-}
-
-/// Generic bus trait
-pub trait Bus: Send + Sync {
-    /// Get bus name
-    fn name(&self) -> &str;
-    
-    /// Get bus type
-    fn bus_type(&self) -> BusType;
-    
-    /// Enumerate devices on bus
-    fn enumerate(&self) -> Result<Vec<Arc<Device>>>;
-    
-    /// Rescan bus for new devices
-    fn rescan(&self) -> Result<()>;
-    
-    /// Match device to driver
-    fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool;
-    
-    /// Add device to bus
-    fn add_device(&self, device: Arc<Device>) -> Result<()>;
-    
-    /// Remove device from bus
-    fn remove_device(&self, id: DeviceId) -> Result<()>;
-    
-    /// Suspend bus
-    fn suspend(&self) -> Result<()> {
-        Ok(())
-    }
-    
-    /// Resume bus
-    fn resume(&self) -> Result<()> {
-        Ok(())
-    }
-}
-
-/// Bus driver trait for bus controllers
-pub trait BusDriver: Driver {
-    /// Initialize bus controller
-    fn init_controller(&self) -> Result<()>;
-    
-    /// Scan bus for devices
-    fn scan_bus(&self) -> Result<Vec<Arc<Device>>>;
-    
-    /// Configure bus settings
-    fn configure_bus(&self, config: BusConfig) -> Result<()>;
-}
-
-/// Bus configuration
-#[derive(Debug, Clone)]
-pub struct BusConfig {
-    pub speed: BusSpeed,
-    pub width: u32,
-    pub max_devices: u32,
-    pub power_budget: u32, // in milliwatts
-}
-
-/// Bus speed settings
-#[derive(Debug, Clone, Copy)]
-pub enum BusSpeed {
-    Low,
-    Full,
-    High,
-    Super,
-    Custom(u32), // in MHz
-}
-
-/// PCI bus implementation
-pub struct PciBus {
-    devices: RwLock<BTreeMap<PciAddress, Arc<Device>>>,
-    config_space: RwLock<PciConfigSpace>,
-}
-
-/// PCI address
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct PciAddress {
-    pub segment: u16,
-    pub bus: u8,
-    pub device: u8,
-    pub function: u8,
-}
-
-impl PciAddress {
-    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
-        Self {
-            segment,
-            bus,
-            device,
-            function,
-        }
-    }
-}
+use super::model::{BusType, DeviceId, Ident, DEVICES};
+use super::probe::register_device;
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER, PCI_CLASS_BRIDGE, PCI_SECONDARY_BUS};
+use crate::serial_println;
 
-/// PCI configuration space
-pub struct PciConfigSpace {
-    data: BTreeMap<PciAddress, PciConfig>,
-}
-
-/// PCI device configuration
-#[derive(Debug, Clone)]
-pub struct PciConfig {
-    pub vendor_id: u16,
-    pub device_id: u16,
-    pub class_code: u32,
-    pub bars: [PciBar; 6],
-    pub irq: u8,
-    pub capabilities: Vec<PciCapability>,
-}
+static PLATFORM_ROOT: Once<Option<DeviceId>> = Once::new();
+static PCI_ROOT: Once<Option<DeviceId>> = Once::new();
 
-/// PCI Base Address Register
-#[derive(Debug, Clone, Copy)]
-pub enum PciBar {
-    None,
-    Memory {
-        base: u64,
-        size: u32,
-        prefetchable: bool,
-        is_64bit: bool,
-    },
-    Io {
-        base: u32,
-        size: u32,
-    },
+fn root(once: &Once<Option<DeviceId>>, name: &str, bus: BusType) -> Option<DeviceId> {
+    *once.call_once(|| register_device(String::from(name), bus, Ident::Root, None).ok())
 }
 
-/// PCI capability
-#[derive(Debug, Clone)]
-pub struct PciCapability {
-    pub id: u8,
-    pub offset: u8,
-    pub data: Vec<u8>,
+/// Enumerate every bus; drivers registered by then bind as devices appear
+pub fn enumerate() {
+    enumerate_platform();
+    enumerate_pci();
+    enumerate_usb();
 }
 
-impl PciBus {
-    /// Create new PCI bus
-    pub fn new() -> Self {
-        Self {
-            devices: RwLock::new(BTreeMap::new()),
-            config_space: RwLock::new(PciConfigSpace {
-                data: BTreeMap::new(),
-            }),
-        }
-    }
-    
-    /// Scan PCI bus for devices
-    fn scan_pci_bus(&self) -> Result<Vec<Arc<Device>>> {
-        let mut found_devices = Vec::new();
-        
-        // Scan all possible PCI addresses
-        for bus in 0..=255u8 {
-            for device in 0..32u8 {
-                for function in 0..8u8 {
-                    let addr = PciAddress::new(0, bus, device, function);
-                    
-                    if let Some(pci_device) = self.probe_pci_device(addr)? {
-                        found_devices.push(pci_device);
-                    }
-                }
-            }
-        }
-        
-        Ok(found_devices)
-    }
-    
-    /// Probe PCI device at address
-    fn probe_pci_device(&self, addr: PciAddress) -> Result<Option<Arc<Device>>> {
-        // Read vendor ID (would be actual PCI config space read)
-        let vendor_id = self.read_config_word(addr, 0x00)?;
-        
-        if vendor_id == 0xFFFF {
-            return Ok(None); // No device
-        }
-        
-        let device_id = self.read_config_word(addr, 0x02)?;
-        let class_code = self.read_config_dword(addr, 0x08)?;
-        
-        // Create device
-        let name = format!("pci:{:04x}:{:04x}", vendor_id, device_id);
-        let class = self.class_from_code(class_code);
-        
-        let device = DeviceBuilder::new(name, class)
-            .capabilities(DeviceCapabilities {
-                dma_capable: true,
-                bus_master: true,
-                wake_capable: false,
-                power_manageable: true,
-                hot_pluggable: self.is_hotplug_slot(addr),
-                iommu_mapped: false,
-            })
-            .build();
-        
-        // Add PCI resources
-        self.add_pci_resources(&device, addr)?;
-        
-        let device = Arc::new(device);
-        
-        // Store in bus registry
-        self.devices.write().insert(addr, device.clone());
-        
-        Ok(Some(device))
-    }
-    
-    /// Read PCI config word
-    fn read_config_word(&self, addr: PciAddress, offset: u16) -> Result<u16> {
-        // Would perform actual PCI config space read
-        Ok(0x0000)
-    }
-    
-    /// Read PCI config dword
-    fn read_config_dword(&self, addr: PciAddress, offset: u16) -> Result<u32> {
-        // Would perform actual PCI config space read
-        Ok(0x00000000)
-    }
-    
-    /// Convert class code to device class
-    fn class_from_code(&self, code: u32) -> DeviceClass {
-        let class = (code >> 24) & 0xFF;
-        let subclass = (code >> 16) & 0xFF;
-        
-        match (class, subclass) {
-            (0x01, _) => DeviceClass::BlockDevice,      // Mass storage
-            (0x02, _) => DeviceClass::NetworkInterface, // Network
-            (0x03, _) => DeviceClass::DisplayController,// Display
-            (0x04, _) => DeviceClass::AudioController,  // Multimedia
-            (0x06, 0x04) => DeviceClass::PciBridge,    // PCI bridge
-            (0x0C, 0x03) => DeviceClass::UsbController,// USB
-            _ => DeviceClass::Unknown,
-        }
-    }
-    
-    /// Check if slot supports hot-plug
-    fn is_hotplug_slot(&self, addr: PciAddress) -> bool {
-        // Would check for hot-plug capability
-        false
-    }
-    
-    /// Add PCI resources to device
-    fn add_pci_resources(&self, device: &Device, addr: PciAddress) -> Result<()> {
-        // Read BARs
-        for i in 0..6 {
-            let bar_offset = 0x10 + (i * 4);
-            let bar_value = self.read_config_dword(addr, bar_offset)?;
-            
-            if bar_value != 0 {
-                // Decode BAR
-                if bar_value & 0x1 == 0 {
-                    // Memory BAR
-                    let base = bar_value & !0xF;
-                    let prefetchable = (bar_value & 0x8) != 0;
-                    
-                    device.add_resource(DeviceResource::Memory {
-                        base: base as u64,
-                        size: 0x1000, // Would probe actual size
-                        flags: MemoryFlags {
-                            prefetchable,
-                            cacheable: false,
-                            readonly: false,
-                            mmio: true,
-                        },
-                    });
-                } else {
-                    // I/O BAR
-                    let base = bar_value & !0x3;
-                    
-                    device.add_resource(DeviceResource::Io {
-                        base: base as u16,
-                        size: 0x100, // Would probe actual size
-                    });
-                }
-            }
-        }
-        
-        // Add interrupt
-        let irq = self.read_config_word(addr, 0x3C)? & 0xFF;
-        if irq != 0 && irq != 0xFF {
-            device.add_resource(DeviceResource::Interrupt {
-                irq: irq as u32,
-                flags: InterruptFlags {
-                    edge_triggered: false,
-                    active_low: true,
-                    shared: true,
-                    msi: false,
-                },
-            });
-        }
-        
-        Ok(())
-    }
+/// Add a device that firmware or the kernel knows is there
+pub fn add_platform_device(name: &'static str, instance: u32) -> Result<DeviceId, &'static str> {
+    let parent = root(&PLATFORM_ROOT, "platform", BusType::Platform);
+    register_device(format!("{}.{}", name, instance), BusType::Platform, Ident::Platform { name, instance }, parent)
 }
 
-impl Bus for PciBus {
-    fn name(&self) -> &str {
-        "pci"
+fn enumerate_platform() {
+    let mut found = Vec::new();
+    for port in crate::serial::ports() {
+        found.push(("serial8250", port.index() as u32));
     }
-    
-    fn bus_type(&self) -> BusType {
-        BusType::Pci
+    // Legacy devices every PC has
+    found.push(("i8042", 0));
+    found.push(("rtc_cmos", 0));
+    found.push(("pcspkr", 0));
+    if crate::time::hpet::present() {
+        found.push(("hpet", 0));
     }
-    
-    fn enumerate(&self) -> Result<Vec<Arc<Device>>> {
-        self.scan_pci_bus()
-    }
-    
-    fn rescan(&self) -> Result<()> {
-        let new_devices = self.scan_pci_bus()?;
-        
-        // Register new devices
-        for device in new_devices {
-            super::driver_manager().register_device(device)?;
+    for (name, instance) in found {
+        if let Err(e) = add_platform_device(name, instance) {
+            serial_println!("driver: cannot add {}.{}: {}", name, instance, e);
         }
-        
-        Ok(())
-    }
-    
-    fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool {
-        // Check if driver supports device class
-        driver.supported_classes().contains(&device.class())
-    }
-    
-    fn add_device(&self, device: Arc<Device>) -> Result<()> {
-        // Would add to PCI device tree
-        Ok(())
-    }
-    
-    fn remove_device(&self, id: DeviceId) -> Result<()> {
-        let mut devices = self.devices.write();
-        
-        // Find and remove device
-        devices.retain(|_, dev| dev.id() != id);
-        
-        Ok(())
     }
 }
 
-/// USB bus implementation
-pub struct UsbBus {
-    devices: RwLock<BTreeMap<UsbAddress, Arc<Device>>>,
-    root_hub: RwLock<Option<Arc<Device>>>,
+/// The name Linux gives a PCI function
+pub fn pci_name(location: &PciLocation) -> String {
+    format!("{:04x}:{:02x}:{:02x}.{}", location.segment, location.bus, location.device, location.function)
 }
 
-/// USB address
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct UsbAddress {
-    pub bus: u8,
-    pub port: u8,
-    pub device: u8,
+fn is_bridge(ident: &Ident) -> bool {
+    matches!(ident, Ident::Pci { class: PCI_CLASS_BRIDGE, subclass: 0x04 | 0x09, .. })
 }
 
-impl UsbBus {
-    /// Create new USB bus
-    pub fn new() -> Self {
-        Self {
-            devices: RwLock::new(BTreeMap::new()),
-            root_hub: RwLock::new(None),
-        }
-    }
-    
-    /// Initialize root hub
-    pub fn init_root_hub(&self) -> Result<()> {
-        let root_hub = Arc::new(Device::new(
-            "usb-root-hub".into(),
-            DeviceClass::UsbController,
-        ));
-        
-        *self.root_hub.write() = Some(root_hub);
-        
-        Ok(())
-    }
-    
-    /// Enumerate USB devices
-    fn enumerate_usb(&self) -> Result<Vec<Arc<Device>>> {
-        let mut devices = Vec::new();
-        
-        // Would enumerate actual USB devices
-        // For now, return empty list
-        
-        Ok(devices)
-    }
+// The bridge whose secondary bus is `bus`, or the host bridge
+fn pci_parent(bus: u8) -> Option<DeviceId> {
+    let bridges: Vec<(DeviceId, PciLocation)> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| is_bridge(&device.ident))
+        .filter_map(|device| match device.ident {
+            Ident::Pci { location, .. } => Some((device.id, location)),
+            _ => None,
+        })
+        .collect();
+    let controller = PCIE_CONTROLLER.lock();
+    bridges
+        .into_iter()
+        .find(|(_, location)| controller.read8(*location, PCI_SECONDARY_BUS) == bus)
+        .map(|(id, _)| id)
+        .or_else(|| root(&PCI_ROOT, "pci0000:00", BusType::Pci))
 }
 
-impl Bus for UsbBus {
-    fn name(&self) -> &str {
-        "usb"
-    }
-    
-    fn bus_type(&self) -> BusType {
-        BusType::Usb
-    }
-    
-    fn enumerate(&self) -> Result<Vec<Arc<Device>>> {
-        self.enumerate_usb()
-    }
-    
-    fn rescan(&self) -> Result<()> {
-        let new_devices = self.enumerate_usb()?;
-        
-        for device in new_devices {
-            super::driver_manager().register_device(device)?;
-        }
-        
-        Ok(())
-    }
-    
-    fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool {
-        driver.supported_classes().contains(&device.class())
-    }
-    
-    fn add_device(&self, device: Arc<Device>) -> Result<()> {
-        Ok(())
-    }
-    
-    fn remove_device(&self, id: DeviceId) -> Result<()> {
-        let mut devices = self.devices.write();
-        devices.retain(|_, dev| dev.id() != id);
-        Ok(())
-    }
+/// Add a PCI function under the bridge it is behind
+pub fn add_pci_device(device: &PciDevice) -> Result<DeviceId, &'static str> {
+    let parent = pci_parent(device.location.bus);
+    register_device(pci_name(&device.location), BusType::Pci, Ident::pci(device), parent)
 }
 
-/// Platform bus for SoC and built-in devices
-pub struct PlatformBus {
-    devices: RwLock<Vec<Arc<Device>>>,
+/// The tree's device for a PCI function
+pub fn find_pci_device(location: PciLocation) -> Option<DeviceId> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| matches!(device.ident, Ident::Pci { location: found, .. } if found == location))
+        .map(|device| device.id)
 }
 
-impl PlatformBus {
-    /// Create new platform bus
-    pub fn new() -> Self {
-        Self {
-            devices: RwLock::new(Vec::new()),
+fn enumerate_pci() {
+    let devices = {
+        let mut controller = PCIE_CONTROLLER.lock();
+        if controller.devices().is_empty() {
+            if let Err(e) = controller.init() {
+                serial_println!("driver: PCI enumeration failed: {}", e);
+                return;
+            }
         }
-    }
-    
-    /// Register platform device
-    pub fn register_platform_device(&self, device: Arc<Device>) -> Result<()> {
-        self.devices.write().push(device.clone());
-        super::driver_manager().register_device(device)?;
-        Ok(())
-    }
-}
-
-impl Bus for PlatformBus {
-    fn name(&self) -> &str {
-        "platform"
-    }
-    
-    fn bus_type(&self) -> BusType {
-        BusType::Platform
-    }
-    
-    fn enumerate(&self) -> Result<Vec<Arc<Device>>> {
-        Ok(self.devices.read().clone())
-    }
-    
-    fn rescan(&self) -> Result<()> {
-        // Platform devices are typically static
-        Ok(())
-    }
-    
-    fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool {
-        // Match by name or compatible property
-        if let Some(compatible) = device.get_property("compatible") {
-            // Check if driver supports this compatible string
-            true
-        } else {
-            driver.supported_classes().contains(&device.class())
+        controller.devices().to_vec()
+    };
+    // Bridges come before the devices behind them
+    for device in &devices {
+        if let Err(e) = add_pci_device(device) {
+            serial_println!("driver: cannot add {}: {}", pci_name(&device.location), e);
         }
     }
-    
-    fn add_device(&self, device: Arc<Device>) -> Result<()> {
-        self.devices.write().push(device);
-        Ok(())
-    }
-    
-    fn remove_device(&self, id: DeviceId) -> Result<()> {
-        self.devices.write().retain(|dev| dev.id() != id);
-        Ok(())
-    }
-}
-
-/// Virtual bus for software devices
-pub struct VirtualBus {
-    devices: RwLock<Vec<Arc<Device>>>,
-    next_id: AtomicU32,
 }
 
-impl VirtualBus {
-    /// Create new virtual bus
-    pub fn new() -> Self {
-        Self {
-            devices: RwLock::new(Vec::new()),
-            next_id: AtomicU32::new(1),
+fn enumerate_usb() {
+    let found: Vec<(u8, Option<u8>, Ident)> = crate::usb::USB_MANAGER
+        .lock()
+        .get_devices()
+        .iter()
+        .map(|device| {
+            let ident = Ident::Usb {
+                vendor: device.device_desc.vendor_id,
+                product: device.device_desc.product_id,
+                class: device.class,
+                subclass: device.subclass,
+                protocol: device.protocol,
+                address: device.address,
+            };
+            (device.address, device.parent_hub, ident)
+        })
+        .collect();
+    if found.is_empty() {
+        return;
+    }
+    let host = DEVICES
+        .lock()
+        .iter()
+        .find(|device| matches!(device.ident, Ident::Pci { class: 0x0C, subclass: 0x03, .. }))
+        .map(|device| device.id);
+    // Hubs are addressed before the devices behind them
+    let mut added: Vec<(u8, DeviceId)> = Vec::new();
+    for (address, hub, ident) in found {
+        let parent = hub.and_then(|hub| added.iter().find(|(address, _)| *address == hub)).map(|(_, id)| *id).or(host);
+        match register_device(format!("usb-{}", address), BusType::Usb, ident, parent) {
+            Ok(id) => added.push((address, id)),
+            Err(e) => serial_println!("driver: cannot add USB device {}: {}", address, e),
         }
     }
-    
-    /// Create virtual device
-    pub fn create_virtual_device(&self, name: String, class: DeviceClass) -> Arc<Device> {
-        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
-        let device_name = format!("virtual-{}-{}", name, id);
-        
-        Arc::new(Device::new(device_name, class))
-    }
 }
-
-impl Bus for VirtualBus {
-    fn name(&self) -> &str {
-        "virtual"
-    }
-    
-    fn bus_type(&self) -> BusType {
-        BusType::Virtual
-    }
-    
-    fn enumerate(&self) -> Result<Vec<Arc<Device>>> {
-        Ok(self.devices.read().clone())
-    }
-    
-    fn rescan(&self) -> Result<()> {
-        Ok(())
-    }
-    
-    fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool {
-        driver.supported_classes().contains(&device.class())
-    }
-    
-    fn add_device(&self, device: Arc<Device>) -> Result<()> {
-        self.devices.write().push(device);
-        Ok(())
-    }
-    
-    fn remove_device(&self, id: DeviceId) -> Result<()> {
-        self.devices.write().retain(|dev| dev.id() != id);
-        Ok(())
-    }
-}
\ No newline at end of file
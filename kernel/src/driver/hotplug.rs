@@ -1,559 +0,0 @@
-//! Hot-plug Support System for Dynamic Device Management
-
-use alloc::{
-    boxed::Box,
-    collections::{BTreeMap, VecDeque},
-    string::String,
-    sync::Arc,
-    vec::Vec,
-};
-use core::{
-    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
-};
-use spin::{Mutex, RwLock};
-
-use super::{
-    Device, DeviceId, DeviceClass, Driver, DriverError, Result,
-    bus::{Bus, BusType},
-    model::DeviceState,
-};
-
-/// Hot-plug event type
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum HotplugEventType {
-    /// Device inserted
-    DeviceAdded,
-    /// Device removed
-    DeviceRemoved,
-    /// Device about to be removed
-    DeviceRemovePending,
-    /// Device configuration changed
-    DeviceChanged,
-    /// Bus rescan requested
-    BusRescan,
-}
-
-/// Hot-plug event
-#[derive(Debug, Clone)]
-pub struct HotplugEvent {
-    /// Event type
-    pub event_type: HotplugEventType,
-    /// Device ID (if applicable)
-    pub device_id: Option<DeviceId>,
-    /// Device (for add events)
-    pub device: Option<Arc<Device>>,
-    /// Bus type
-    pub bus_type: Option<BusType>,
-    /// Event timestamp
-    pub timestamp: u64,
-    /// Event sequence number
-    pub sequence: u64,
-}
-
-/// Hot-plug notification callback
-pub type HotplugCallback = Box<dyn Fn(&HotplugEvent) + Send + Sync>;
-
-/// Hot-plug slot information
-#[derive(Debug, Clone)]
-pub struct HotplugSlot {
-    /// Slot name
-    pub name: String,
-    /// Bus type
-    pub bus_type: BusType,
-    /// Slot number
-    pub slot_number: u32,
-    /// Current device (if occupied)
-    pub device_id: Option<DeviceId>,
-    /// Slot capabilities
-    pub capabilities: SlotCapabilities,
-    /// Slot state
-    pub state: SlotState,
-}
-
-/// Slot capabilities
-#[derive(Debug, Clone, Copy, Default)]
-pub struct SlotCapabilities {
-    /// Supports surprise removal
-    pub surprise_removal: bool,
-    /// Has attention indicator
-    pub attention_indicator: bool,
-    /// Has power indicator
-    pub power_indicator: bool,
-    /// Has power control
-    pub power_control: bool,
-    /// Has MRL sensor (mechanical retention latch)
-    pub mrl_sensor: bool,
-    /// Has presence detect
-    pub presence_detect: bool,
-}
-
-/// Slot state
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum SlotState {
-    Empty,
-    Occupied,
-    PoweringOn,
-    PoweringOff,
-    Error,
-}
-
-/// PCIe hot-plug controller
-pub struct PcieHotplugController {
-    /// Managed slots
-    slots: RwLock<BTreeMap<u32, HotplugSlot>>,
-    /// Pending removals
-    pending_removals: Mutex<Vec<DeviceId>>,
-    /// Controller enabled
-    enabled: AtomicBool,
-}
-
-impl PcieHotplugController {
-    /// Create new PCIe hot-plug controller
-    pub fn new() -> Self {
-        Self {
-            slots: RwLock::new(BTreeMap::new()),
-            pending_removals: Mutex::new(Vec::new()),
-            enabled: AtomicBool::new(false),
-        }
-    }
-    
-    /// Initialize controller
-    pub fn init(&self) -> Result<()> {
-        self.enabled.store(true, Ordering::Release);
-        
-        // Scan for hot-plug capable slots
-        self.scan_slots()?;
-        
-        Ok(())
-    }
-    
-    /// Scan for hot-plug slots
-    fn scan_slots(&self) -> Result<()> {
-        // Would scan PCI configuration space for hot-plug capable slots
-        
-        // Example: Add a simulated slot
-        let slot = HotplugSlot {
-            name: "PCIe Slot 1".into(),
-            bus_type: BusType::Pci,
-            slot_number: 1,
-            device_id: None,
-            capabilities: SlotCapabilities {
-                surprise_removal: true,
-                attention_indicator: true,
-                power_indicator: true,
-                power_control: true,
-                mrl_sensor: false,
-                presence_detect: true,
-            },
-            state: SlotState::Empty,
-        };
-        
-        self.slots.write().insert(1, slot);
-        
-        Ok(())
-    }
-    
-    /// Handle slot interrupt
-    pub fn handle_slot_interrupt(&self, slot_number: u32) -> Result<()> {
-        let mut slots = self.slots.write();
-        
-        if let Some(slot) = slots.get_mut(&slot_number) {
-            // Check presence detect change
-            let present = self.check_presence(slot_number)?;
-            
-            if present && slot.state == SlotState::Empty {
-                // Device inserted
-                self.handle_insertion(slot)?;
-            } else if !present && slot.state == SlotState::Occupied {
-                // Device removed
-                self.handle_removal(slot)?;
-            }
-        }
-        
-        Ok(())
-    }
-    
-    /// Handle device insertion
-    fn handle_insertion(&self, slot: &mut HotplugSlot) -> Result<()> {
-        slot.state = SlotState::PoweringOn;
-        
-        // Power on slot
-        self.power_on_slot(slot.slot_number)?;
-        
-        // Wait for link up
-        self.wait_for_link(slot.slot_number)?;
-        
-        // Enumerate device
-        let device = self.enumerate_device(slot.slot_number)?;
-        
-        slot.device_id = Some(device.id());
-        slot.state = SlotState::Occupied;
-        
-        // Send hot-plug event
-        hotplug_manager().send_event(HotplugEvent {
-            event_type: HotplugEventType::DeviceAdded,
-            device_id: Some(device.id()),
-            device: Some(device),
-            bus_type: Some(BusType::Pci),
-            timestamp: self.current_time(),
-            sequence: self.next_sequence(),
-        });
-        
-        Ok(())
-    }
-    
-    /// Handle device removal
-    fn handle_removal(&self, slot: &mut HotplugSlot) -> Result<()> {
-        if let Some(device_id) = slot.device_id {
-            // Check if surprise removal
-            if !slot.capabilities.surprise_removal {
-                // Send pending removal notification
-                hotplug_manager().send_event(HotplugEvent {
-                    event_type: HotplugEventType::DeviceRemovePending,
-                    device_id: Some(device_id),
-                    device: None,
-                    bus_type: Some(BusType::Pci),
-                    timestamp: self.current_time(),
-                    sequence: self.next_sequence(),
-                });
-                
-                // Wait for driver to release device
-                self.pending_removals.lock().push(device_id);
-                
-                // Would wait for acknowledgment
-            }
-            
-            slot.state = SlotState::PoweringOff;
-            
-            // Remove device
-            self.remove_device(device_id)?;
-            
-            // Power off slot
-            self.power_off_slot(slot.slot_number)?;
-            
-            slot.device_id = None;
-            slot.state = SlotState::Empty;
-            
-            // Send removal event
-            hotplug_manager().send_event(HotplugEvent {
-                event_type: HotplugEventType::DeviceRemoved,
-                device_id: Some(device_id),
-                device: None,
-                bus_type: Some(BusType::Pci),
-                timestamp: self.current_time(),
-                sequence: self.next_sequence(),
-            });
-        }
-        
-        Ok(())
-    }
-    
-    /// Check slot presence
-    fn check_presence(&self, slot_number: u32) -> Result<bool> {
-        // Would read presence detect bit
-        Ok(false)
-    }
-    
-    /// Power on slot
-    fn power_on_slot(&self, slot_number: u32) -> Result<()> {
-        // Would control slot power
-        Ok(())
-    }
-    
-    /// Power off slot
-    fn power_off_slot(&self, slot_number: u32) -> Result<()> {
-        // Would control slot power
-        Ok(())
-    }
-    
-    /// Wait for link up
-    fn wait_for_link(&self, slot_number: u32) -> Result<()> {
-        // Would wait for PCIe link training
-        Ok(())
-    }
-    
-    /// Enumerate device in slot
-    fn enumerate_device(&self, slot_number: u32) -> Result<Arc<Device>> {
-        // Would perform PCI enumeration
-        let device = Arc::new(Device::new(
-            format!("pcie-slot-{}", slot_number),
-            DeviceClass::Unknown,
-        ));
-        
-        Ok(device)
-    }
-    
-    /// Remove device
-    fn remove_device(&self, device_id: DeviceId) -> Result<()> {
-        // Would remove from device tree
-        Ok(())
-    }
-    
-    fn current_time(&self) -> u64 {
-        0 // Would get actual time
-    }
-    
-    fn next_sequence(&self) -> u64 {
-        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
-        SEQUENCE.fetch_add(1, Ordering::Relaxed)
-    }
-}
-
-/// USB hot-plug handler
-pub struct UsbHotplugHandler {
-    /// Port status
-    ports: RwLock<BTreeMap<u8, UsbPortStatus>>,
-    /// Enabled flag
-    enabled: AtomicBool,
-}
-
-/// USB port status
-#[derive(Debug, Clone)]
-struct UsbPortStatus {
-    port_number: u8,
-    connected: bool,
-    device_id: Option<DeviceId>,
-    speed: UsbSpeed,
-}
-
-/// USB speed
-#[derive(Debug, Clone, Copy)]
-enum UsbSpeed {
-    Low,
-    Full,
-    High,
-    Super,
-    SuperPlus,
-}
-
-impl UsbHotplugHandler {
-    /// Create new USB hot-plug handler
-    pub fn new() -> Self {
-        Self {
-            ports: RwLock::new(BTreeMap::new()),
-            enabled: AtomicBool::new(false),
-        }
-    }
-    
-    /// Handle port status change
-    pub fn handle_port_change(&self, port: u8) -> Result<()> {
-        let mut ports = self.ports.write();
-        
-        let status = self.read_port_status(port)?;
-        
-        if let Some(port_status) = ports.get_mut(&port) {
-            if status.connected && !port_status.connected {
-                // Device connected
-                self.handle_usb_connect(port, &status)?;
-                port_status.connected = true;
-            } else if !status.connected && port_status.connected {
-                // Device disconnected
-                self.handle_usb_disconnect(port)?;
-                port_status.connected = false;
-                port_status.device_id = None;
-            }
-        }
-        
-        Ok(())
-    }
-    
-    /// Handle USB device connection
-    fn handle_usb_connect(&self, port: u8, status: &UsbPortStatus) -> Result<()> {
-        // Would enumerate USB device
-        let device = Arc::new(Device::new(
-            format!("usb-port-{}", port),
-            DeviceClass::Unknown,
-        ));
-        
-        // Send hot-plug event
-        hotplug_manager().send_event(HotplugEvent {
-            event_type: HotplugEventType::DeviceAdded,
-            device_id: Some(device.id()),
-            device: Some(device.clone()),
-            bus_type: Some(BusType::Usb),
-            timestamp: 0,
-            sequence: 0,
-        });
-        
-        Ok(())
-    }
-    
-    /// Handle USB device disconnection
-    fn handle_usb_disconnect(&self, port: u8) -> Result<()> {
-        let ports = self.ports.read();
-        
-        if let Some(port_status) = ports.get(&port) {
-            if let Some(device_id) = port_status.device_id {
-                // Send removal event
-                hotplug_manager().send_event(HotplugEvent {
-                    event_type: HotplugEventType::DeviceRemoved,
-                    device_id: Some(device_id),
-                    device: None,
-                    bus_type: Some(BusType::Usb),
-                    timestamp: 0,
-                    sequence: 0,
-                });
-            }
-        }
-        
-        Ok(())
-    }
-    
-    /// Read port status
-    fn read_port_status(&self, port: u8) -> Result<UsbPortStatus> {
-        // Would read actual USB port status
-        Ok(UsbPortStatus {
-            port_number: port,
-            connected: false,
-            device_id: None,
-            speed: UsbSpeed::High,
-        })
-    }
-}
-
-/// Global hot-plug manager
-pub struct HotplugManager {
-    /// Event queue
-    event_queue: Mutex<VecDeque<HotplugEvent>>,
-    /// Event callbacks
-    callbacks: RwLock<Vec<HotplugCallback>>,
-    /// PCIe controller
-    pcie_controller: PcieHotplugController,
-    /// USB handler
-    usb_handler: UsbHotplugHandler,
-    /// Statistics
-    stats: HotplugStats,
-}
-
-/// Hot-plug statistics
-struct HotplugStats {
-    events_sent: AtomicU64,
-    devices_added: AtomicU64,
-    devices_removed: AtomicU64,
-    surprise_removals: AtomicU64,
-}
-
-impl HotplugManager {
-    /// Create new hot-plug manager
-    pub const fn new() -> Self {
-        Self {
-            event_queue: Mutex::new(VecDeque::new()),
-            callbacks: RwLock::new(Vec::new()),
-            pcie_controller: PcieHotplugController::new(),
-            usb_handler: UsbHotplugHandler::new(),
-            stats: HotplugStats {
-                events_sent: AtomicU64::new(0),
-                devices_added: AtomicU64::new(0),
-                devices_removed: AtomicU64::new(0),
-                surprise_removals: AtomicU64::new(0),
-            },
-        }
-    }
-    
-    /// Initialize hot-plug subsystem
-    pub fn init(&self) -> Result<()> {
-        // Initialize controllers
-        self.pcie_controller.init()?;
-        
-        Ok(())
-    }
-    
-    /// Register callback for hot-plug events
-    pub fn register_callback(&self, callback: HotplugCallback) {
-        self.callbacks.write().push(callback);
-    }
-    
-    /// Send hot-plug event
-    pub fn send_event(&self, event: HotplugEvent) {
-        // Update statistics
-        match event.event_type {
-            HotplugEventType::DeviceAdded => {
-                self.stats.devices_added.fetch_add(1, Ordering::Relaxed);
-            }
-            HotplugEventType::DeviceRemoved => {
-                self.stats.devices_removed.fetch_add(1, Ordering::Relaxed);
-            }
-            _ => {}
-        }
-        
-        self.stats.events_sent.fetch_add(1, Ordering::Relaxed);
-        
-        // Queue event
-        self.event_queue.lock().push_back(event.clone());
-        
-        // Call callbacks
-        let callbacks = self.callbacks.read();
-        for callback in callbacks.iter() {
-            callback(&event);
-        }
-    }
-    
-    /// Process event queue
-    pub fn process_events(&self) -> Result<()> {
-        while let Some(event) = self.event_queue.lock().pop_front() {
-            self.handle_event(event)?;
-        }
-        
-        Ok(())
-    }
-    
-    /// Handle hot-plug event
-    fn handle_event(&self, event: HotplugEvent) -> Result<()> {
-        match event.event_type {
-            HotplugEventType::DeviceAdded => {
-                if let Some(device) = event.device {
-                    // Register device
-                    super::driver_manager().register_device(device)?;
-                }
-            }
-            HotplugEventType::DeviceRemoved => {
-                if let Some(device_id) = event.device_id {
-                    // Remove device
-                    // Would remove from device tree
-                }
-            }
-            HotplugEventType::BusRescan => {
-                if let Some(bus_type) = event.bus_type {
-                    // Trigger bus rescan
-                    self.rescan_bus(bus_type)?;
-                }
-            }
-            _ => {}
-        }
-        
-        Ok(())
-    }
-    
-    /// Rescan bus for changes
-    fn rescan_bus(&self, bus_type: BusType) -> Result<()> {
-        // Would trigger bus-specific rescan
-        Ok(())
-    }
-    
-    /// Get hot-plug statistics
-    pub fn statistics(&self) -> HotplugStatistics {
-        HotplugStatistics {
-            events_sent: self.stats.events_sent.load(Ordering::Relaxed),
-            devices_added: self.stats.devices_added.load(Ordering::Relaxed),
-            devices_removed: self.stats.devices_removed.load(Ordering::Relaxed),
-            surprise_removals: self.stats.surprise_removals.load(Ordering::Relaxed),
-        }
-    }
-}
-
-/// Hot-plug statistics
-#[derive(Debug, Clone, Copy)]
-pub struct HotplugStatistics {
-    pub events_sent: u64,
-    pub devices_added: u64,
-    pub devices_removed: u64,
-    pub surprise_removals: u64,
-}
-
-/// Global hot-plug manager instance
-static HOTPLUG_MANAGER: HotplugManager = HotplugManager::new();
-
-/// Get global hot-plug manager
-pub fn hotplug_manager() -> &'static HotplugManager {
-    &HOTPLUG_MANAGER
-}
\ No newline at end of file
@@ -1,583 +1,267 @@
-//! Device Model - Core device tree representation and management
+//! Devices and the tree they form
 
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::{String, ToString},
-    sync::{Arc, Weak},
-    vec::Vec,
-};
-use core::{
-    fmt,
-    sync::atomic::{AtomicU32, AtomicU64, Ordering},
-};
-use spin::{Mutex, RwLock};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 
-use super::{Driver, DriverError, Result};
+use spin::Mutex;
 
-/// Unique device identifier
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct DeviceId(u64);
+use crate::pcie::{PciDevice, PciLocation};
 
-impl DeviceId {
-    /// Generate new unique device ID
-    pub fn new() -> Self {
-        static COUNTER: AtomicU64 = AtomicU64::new(1);
-        Self(COUNTER.fetch_add(1, Ordering::Relaxed))
-    }
-    
-    /// Create from raw value (for persistent IDs)
-    pub const fn from_raw(id: u64) -> Self {
-        Self(id)
-    }
-    
-    /// Get raw value
-    pub const fn as_raw(&self) -> u64 {
-        self.0
+/// A device's handle, never reused while the kernel runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(u32);
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
-/// Device class for categorization
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum DeviceClass {
-    // Storage devices
-    BlockDevice,
-    CharDevice,
-    NvmeDevice,
-    ScsiDevice,
-    
-    // Network devices
-    NetworkInterface,
-    WirelessInterface,
-    
-    // Display/Graphics
-    DisplayController,
-    GraphicsAdapter,
-    
-    // Input devices
-    Keyboard,
-    Mouse,
-    Touchpad,
-    Touchscreen,
-    
-    // Audio devices
-    AudioController,
-    AudioCodec,
-    
-    // Bus controllers
-    PciBridge,
-    UsbController,
-    I2cController,
-    SpiController,
-    
-    // System devices
-    SystemController,
-    InterruptController,
-    DmaController,
-    TimerDevice,
-    
-    // Generic
-    Unknown,
+pub enum BusType {
     Platform,
-    Virtual,
+    Pci,
+    Usb,
 }
 
-/// Device state
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum DeviceState {
-    Uninitialized,
-    Probing,
-    Bound,
-    Active,
-    Suspended,
-    Failed,
-    Removed,
+impl BusType {
+    pub const ALL: [BusType; 3] = [BusType::Platform, BusType::Pci, BusType::Usb];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BusType::Platform => "platform",
+            BusType::Pci => "pci",
+            BusType::Usb => "usb",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BusType> {
+        Self::ALL.into_iter().find(|bus| bus.name() == name)
+    }
 }
 
-/// Device resources
-#[derive(Debug, Clone)]
-pub enum DeviceResource {
-    Memory {
-        base: u64,
-        size: usize,
-        flags: MemoryFlags,
-    },
-    Io {
-        base: u16,
-        size: u16,
+/// What a device says it is, which drivers match against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ident {
+    /// The root of a bus, which no driver binds
+    Root,
+    /// A device at a fixed place that nothing enumerates, by driver name
+    Platform {
+        name: &'static str,
+        instance: u32,
     },
-    Interrupt {
-        irq: u32,
-        flags: InterruptFlags,
+    Pci {
+        vendor: u16,
+        device: u16,
+        class: u8,
+        subclass: u8,
+        prog_if: u8,
+        location: PciLocation,
     },
-    Dma {
-        channel: u32,
-        mask: u64,
+    Usb {
+        vendor: u16,
+        product: u16,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        address: u8,
     },
 }
 
-/// Memory resource flags
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryFlags {
-    pub prefetchable: bool,
-    pub cacheable: bool,
-    pub readonly: bool,
-    pub mmio: bool,
-}
-
-/// Interrupt flags
-#[derive(Debug, Clone, Copy)]
-pub struct InterruptFlags {
-    pub edge_triggered: bool,
-    pub active_low: bool,
-    pub shared: bool,
-    pub msi: bool,
-}
-
-/// Device properties for configuration
-pub struct DeviceProperty {
-    pub name: String,
-    pub value: PropertyValue,
-}
-
-/// Property values
-pub enum PropertyValue {
-    String(String),
-    U32(u32),
-    U64(u64),
-    Bool(bool),
-    Binary(Vec<u8>),
-}
-
-/// Core device structure
-pub struct Device {
-    /// Unique device ID
-    id: DeviceId,
-    
-    /// Device name
-    name: String,
-    
-    /// Device class
-    class: DeviceClass,
-    
-    /// Current state
-    state: RwLock<DeviceState>,
-    
-    /// Parent device (weak reference to prevent cycles)
-    parent: RwLock<Option<Weak<Device>>>,
-    
-    /// Child devices
-    children: RwLock<Vec<Arc<Device>>>,
-    
-    /// Bound driver name
-    driver: RwLock<Option<String>>,
-    
-    /// Device resources
-    resources: RwLock<Vec<DeviceResource>>,
-    
-    /// Device properties
-    properties: RwLock<BTreeMap<String, PropertyValue>>,
-    
-    /// Reference count
-    refcount: AtomicU32,
-    
-    /// Device-specific data
-    private_data: Mutex<Option<Box<dyn Any + Send + Sync>>>,
-    
-    /// Device capabilities
-    capabilities: DeviceCapabilities,
-}
-
-/// Device capabilities
-#[derive(Debug, Clone, Copy, Default)]
-pub struct DeviceCapabilities {
-    pub dma_capable: bool,
-    pub bus_master: bool,
-    pub wake_capable: bool,
-    pub power_manageable: bool,
-    pub hot_pluggable: bool,
-    pub iommu_mapped: bool,
-}
-
-impl Device {
-    /// Create new device
-    pub fn new(name: String, class: DeviceClass) -> Self {
-        Self {
-            id: DeviceId::new(),
-            name,
-            class,
-            state: RwLock::new(DeviceState::Uninitialized),
-            parent: RwLock::new(None),
-            children: RwLock::new(Vec::new()),
-            driver: RwLock::new(None),
-            resources: RwLock::new(Vec::new()),
-            properties: RwLock::new(BTreeMap::new()),
-            refcount: AtomicU32::new(1),
-            private_data: Mutex::new(None),
-            capabilities: DeviceCapabilities::default(),
-        }
-    }
-    
-    /// Get device ID
-    pub fn id(&self) -> DeviceId {
-        self.id
-    }
-    
-    /// Get device name
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-    
-    /// Get device class
-    pub fn class(&self) -> DeviceClass {
-        self.class
-    }
-    
-    /// Get current state
-    pub fn state(&self) -> DeviceState {
-        *self.state.read()
-    }
-    
-    /// Set device state
-    pub fn set_state(&self, state: DeviceState) {
-        *self.state.write() = state;
-    }
-    
-    /// Check if device is bound to a driver
-    pub fn is_bound(&self) -> bool {
-        self.driver.read().is_some()
-    }
-    
-    /// Get driver name if bound
-    pub fn driver_name(&self) -> Option<String> {
-        self.driver.read().clone()
-    }
-    
-    /// Bind driver to device
-    pub fn bind_driver(&self, driver_name: String) -> Result<()> {
-        let mut driver = self.driver.write();
-        
-        if driver.is_some() {
-            return Err(DriverError::AlreadyExists);
-        }
-        
-        *driver = Some(driver_name);
-        self.set_state(DeviceState::Bound);
-        
-        Ok(())
-    }
-    
-    /// Unbind driver from device
-    pub fn unbind_driver(&self) -> Result<()> {
-        let mut driver = self.driver.write();
-        
-        if driver.is_none() {
-            return Err(DriverError::NotFound);
-        }
-        
-        *driver = None;
-        self.set_state(DeviceState::Uninitialized);
-        
-        Ok(())
-    }
-    
-    /// Set parent device
-    pub fn set_parent(&self, parent: Weak<Device>) {
-        *self.parent.write() = Some(parent);
-    }
-    
-    /// Get parent device
-    pub fn parent(&self) -> Option<Arc<Device>> {
-        self.parent.read().as_ref()?.upgrade()
-    }
-    
-    /// Add child device
-    pub fn add_child(&self, child: Arc<Device>) {
-        child.set_parent(Arc::downgrade(&Arc::new(self.clone())));
-        self.children.write().push(child);
-    }
-    
-    /// Remove child device
-    pub fn remove_child(&self, id: DeviceId) -> Result<()> {
-        let mut children = self.children.write();
-        
-        if let Some(pos) = children.iter().position(|c| c.id() == id) {
-            children.remove(pos);
-            Ok(())
-        } else {
-            Err(DriverError::NotFound)
+impl Ident {
+    pub fn pci(device: &PciDevice) -> Self {
+        Ident::Pci {
+            vendor: device.vendor_id,
+            device: device.device_id,
+            class: device.class,
+            subclass: device.subclass,
+            prog_if: device.prog_if,
+            location: device.location,
         }
     }
-    
-    /// Get children
-    pub fn children(&self) -> Vec<Arc<Device>> {
-        self.children.read().clone()
-    }
-    
-    /// Add resource
-    pub fn add_resource(&self, resource: DeviceResource) {
-        self.resources.write().push(resource);
-    }
-    
-    /// Get resources
-    pub fn resources(&self) -> Vec<DeviceResource> {
-        self.resources.read().clone()
-    }
-    
-    /// Find resource by type
-    pub fn find_resource<F>(&self, predicate: F) -> Option<DeviceResource>
-    where
-        F: Fn(&DeviceResource) -> bool,
-    {
-        self.resources.read().iter().find(|r| predicate(r)).cloned()
-    }
-    
-    /// Set property
-    pub fn set_property(&self, name: String, value: PropertyValue) {
-        self.properties.write().insert(name, value);
-    }
-    
-    /// Get property
-    pub fn get_property(&self, name: &str) -> Option<PropertyValue> {
-        self.properties.read().get(name).cloned()
-    }
-    
-    /// Set private data
-    pub fn set_private_data<T: Any + Send + Sync + 'static>(&self, data: T) {
-        *self.private_data.lock() = Some(Box::new(data));
-    }
-    
-    /// Get private data
-    pub fn get_private_data<T: Any + Send + Sync + 'static>(&self) -> Option<Box<T>> {
-        let mut data = self.private_data.lock();
-        
-        if let Some(any_box) = data.take() {
-            if let Ok(concrete) = any_box.downcast::<T>() {
-                return Some(concrete);
+
+    /// The identity in the form Linux gives it in `modalias`
+    pub fn modalias(&self) -> String {
+        match *self {
+            Ident::Root => String::new(),
+            Ident::Platform { name, .. } => format!("platform:{}", name),
+            Ident::Pci { vendor, device, class, subclass, prog_if, .. } => {
+                format!("pci:v{:08X}d{:08X}bc{:02X}sc{:02X}i{:02X}", vendor, device, class, subclass, prog_if)
             }
-        }
-        
-        None
-    }
-    
-    /// Increment reference count
-    pub fn get(&self) {
-        self.refcount.fetch_add(1, Ordering::Relaxed);
-    }
-    
-    /// Decrement reference count
-    pub fn put(&self) {
-        self.refcount.fetch_sub(1, Ordering::Relaxed);
-    }
-    
-    /// Get reference count
-    pub fn refcount(&self) -> u32 {
-        self.refcount.load(Ordering::Relaxed)
-    }
-    
-    /// Get device capabilities
-    pub fn capabilities(&self) -> &DeviceCapabilities {
-        &self.capabilities
-    }
-    
-    /// Walk device tree from this node
-    pub fn walk_tree<F>(&self, mut visitor: F) -> Result<()>
-    where
-        F: FnMut(&Device) -> Result<()>,
-    {
-        visitor(self)?;
-        
-        for child in self.children() {
-            child.walk_tree(&mut visitor)?;
-        }
-        
-        Ok(())
-    }
-    
-    /// Find device in tree by ID
-    pub fn find_device(&self, id: DeviceId) -> Option<Arc<Device>> {
-        if self.id == id {
-            return Some(Arc::new(self.clone()));
-        }
-        
-        for child in self.children() {
-            if let Some(found) = child.find_device(id) {
-                return Some(found);
+            Ident::Usb { vendor, product, class, subclass, protocol, .. } => {
+                format!("usb:v{:04X}p{:04X}dc{:02X}dsc{:02X}dp{:02X}", vendor, product, class, subclass, protocol)
             }
         }
-        
-        None
-    }
-    
-    /// Get device path in tree
-    pub fn device_path(&self) -> String {
-        let mut path = Vec::new();
-        let mut current = Some(Arc::new(self.clone()));
-        
-        while let Some(dev) = current {
-            path.push(dev.name().to_string());
-            current = dev.parent();
-        }
-        
-        path.reverse();
-        path.join("/")
     }
 }
 
-impl Clone for Device {
-    fn clone(&self) -> Self {
-        self.get(); // Increment refcount
-        
-        Self {
-            id: self.id,
-            name: self.name.clone(),
-            class: self.class,
-            state: RwLock::new(*self.state.read()),
-            parent: RwLock::new(self.parent.read().clone()),
-            children: RwLock::new(self.children.read().clone()),
-            driver: RwLock::new(self.driver.read().clone()),
-            resources: RwLock::new(self.resources.read().clone()),
-            properties: RwLock::new(self.properties.read().clone()),
-            refcount: AtomicU32::new(self.refcount.load(Ordering::Relaxed)),
-            private_data: Mutex::new(None), // Don't clone private data
-            capabilities: self.capabilities,
-        }
-    }
+/// An entry in a driver's table of the devices it handles; `None` fields
+/// match anything
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    Platform(&'static str),
+    PciId { vendor: u16, device: u16 },
+    PciClass { class: u8, subclass: u8, prog_if: Option<u8> },
+    UsbId { vendor: u16, product: u16 },
+    UsbClass { class: u8, subclass: Option<u8>, protocol: Option<u8> },
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        // Cleanup when last reference is dropped
-        if self.refcount.load(Ordering::Relaxed) == 0 {
-            // Unbind driver if bound
-            let _ = self.unbind_driver();
-            
-            // Clear children
-            self.children.write().clear();
+impl Match {
+    pub fn matches(&self, ident: &Ident) -> bool {
+        let any = |wanted: Option<u8>, value: u8| wanted.map_or(true, |wanted| wanted == value);
+        match (*self, *ident) {
+            (Match::Platform(wanted), Ident::Platform { name, .. }) => wanted == name,
+            (Match::PciId { vendor, device }, Ident::Pci { vendor: v, device: d, .. }) => vendor == v && device == d,
+            (Match::PciClass { class, subclass, prog_if }, Ident::Pci { class: c, subclass: s, prog_if: p, .. }) => {
+                class == c && subclass == s && any(prog_if, p)
+            }
+            (Match::UsbId { vendor, product }, Ident::Usb { vendor: v, product: p, .. }) => vendor == v && product == p,
+            (Match::UsbClass { class, subclass, protocol }, Ident::Usb { class: c, subclass: s, protocol: p, .. }) => {
+                class == c && any(subclass, s) && any(protocol, p)
+            }
+            _ => false,
         }
     }
 }
 
-impl fmt::Debug for Device {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Device")
-            .field("id", &self.id)
-            .field("name", &self.name)
-            .field("class", &self.class)
-            .field("state", &self.state())
-            .field("driver", &self.driver_name())
-            .field("children", &self.children().len())
-            .finish()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// No driver matched, or none has been tried yet
+    Unbound,
+    Bound,
+    /// A driver is waiting for something before it can bind
+    Deferred(&'static str),
+    /// A driver's probe failed, or the device's resources could not be
+    /// found; no other driver is tried
+    Failed(&'static str),
+    /// Turned off by the administrator: no driver, no resources
+    Disabled,
 }
 
-impl Clone for PropertyValue {
-    fn clone(&self) -> Self {
+impl DeviceState {
+    pub fn name(&self) -> &'static str {
         match self {
-            Self::String(s) => Self::String(s.clone()),
-            Self::U32(v) => Self::U32(*v),
-            Self::U64(v) => Self::U64(*v),
-            Self::Bool(v) => Self::Bool(*v),
-            Self::Binary(v) => Self::Binary(v.clone()),
+            DeviceState::Unbound => "unbound",
+            DeviceState::Bound => "bound",
+            DeviceState::Deferred(_) => "deferred",
+            DeviceState::Failed(_) => "failed",
+            DeviceState::Disabled => "disabled",
         }
     }
 }
 
-/// Device builder for convenient construction
-pub struct DeviceBuilder {
-    device: Device,
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub id: DeviceId,
+    /// Unique among its siblings
+    pub name: String,
+    pub bus: BusType,
+    pub ident: Ident,
+    pub parent: Option<DeviceId>,
+    pub children: Vec<DeviceId>,
+    /// The bound driver's name
+    pub driver: Option<&'static str>,
+    pub state: DeviceState,
 }
 
-impl DeviceBuilder {
-    /// Create new device builder
-    pub fn new(name: String, class: DeviceClass) -> Self {
-        Self {
-            device: Device::new(name, class),
-        }
-    }
-    
-    /// Set capabilities
-    pub fn capabilities(mut self, caps: DeviceCapabilities) -> Self {
-        self.device.capabilities = caps;
-        self
+pub struct DeviceTree {
+    devices: BTreeMap<DeviceId, Device>,
+    next_id: u32,
+}
+
+impl DeviceTree {
+    const fn new() -> Self {
+        Self { devices: BTreeMap::new(), next_id: 1 }
     }
-    
-    /// Add resource
-    pub fn resource(self, resource: DeviceResource) -> Self {
-        self.device.add_resource(resource);
-        self
+
+    pub fn get(&self, id: DeviceId) -> Option<&Device> {
+        self.devices.get(&id)
     }
-    
-    /// Add property
-    pub fn property(self, name: String, value: PropertyValue) -> Self {
-        self.device.set_property(name, value);
-        self
+
+    /// Every device, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices.values()
     }
-    
-    /// Build device
-    pub fn build(self) -> Device {
-        self.device
+
+    pub fn roots(&self) -> impl Iterator<Item = &Device> {
+        self.iter().filter(|device| device.parent.is_none())
     }
-}
 
-/// Device tree walker for tree operations
-pub struct DeviceTreeWalker;
+    pub fn children(&self, id: DeviceId) -> impl Iterator<Item = &Device> {
+        self.get(id).into_iter().flat_map(|device| &device.children).filter_map(|child| self.get(*child))
+    }
 
-impl DeviceTreeWalker {
-    /// Count devices in tree
-    pub fn count_devices(root: &Device) -> usize {
-        let mut count = 1;
-        
-        for child in root.children() {
-            count += Self::count_devices(&child);
+    /// The device's names from its root down, separated by slashes
+    pub fn path(&self, id: DeviceId) -> String {
+        let mut names = Vec::new();
+        let mut next = self.get(id);
+        while let Some(device) = next {
+            names.push(device.name.as_str());
+            next = device.parent.and_then(|parent| self.get(parent));
         }
-        
-        count
+        names.reverse();
+        names.join("/")
     }
-    
-    /// Find devices by class
-    pub fn find_by_class(root: &Device, class: DeviceClass) -> Vec<Arc<Device>> {
-        let mut devices = Vec::new();
-        
-        if root.class() == class {
-            devices.push(Arc::new(root.clone()));
-        }
-        
-        for child in root.children() {
-            devices.extend(Self::find_by_class(&child, class));
+
+    /// The device at a path as `path` gives it
+    pub fn lookup(&self, path: &str) -> Option<&Device> {
+        let mut names = path.split('/').filter(|name| !name.is_empty());
+        let first = names.next()?;
+        let mut device = self.roots().find(|device| device.name == first)?;
+        for name in names {
+            device = self.children(device.id).find(|child| child.name == name)?;
         }
-        
-        devices
+        Some(device)
     }
-    
-    /// Find devices by driver
-    pub fn find_by_driver(root: &Device, driver_name: &str) -> Vec<Arc<Device>> {
-        let mut devices = Vec::new();
-        
-        if root.driver_name().as_deref() == Some(driver_name) {
-            devices.push(Arc::new(root.clone()));
+
+    pub(super) fn insert(
+        &mut self,
+        name: String,
+        bus: BusType,
+        ident: Ident,
+        parent: Option<DeviceId>,
+    ) -> Result<DeviceId, &'static str> {
+        let taken = match parent {
+            Some(parent) => {
+                self.get(parent).ok_or("parent device not found")?;
+                self.children(parent).any(|sibling| sibling.name == name)
+            }
+            None => self.roots().any(|root| root.name == name),
+        };
+        if taken {
+            return Err("device name in use");
         }
-        
-        for child in root.children() {
-            devices.extend(Self::find_by_driver(&child, driver_name));
+        let id = DeviceId(self.next_id);
+        self.next_id += 1;
+        self.devices.insert(
+            id,
+            Device { id, name, bus, ident, parent, children: Vec::new(), driver: None, state: DeviceState::Unbound },
+        );
+        if let Some(parent) = parent.and_then(|parent| self.devices.get_mut(&parent)) {
+            parent.children.push(id);
         }
-        
-        devices
+        Ok(id)
     }
-    
-    /// Print device tree
-    pub fn print_tree(root: &Device, indent: usize) {
-        let spaces = " ".repeat(indent);
-        println!("{}[{}] {} ({:?})", spaces, root.id().as_raw(), root.name(), root.class());
-        
-        if let Some(driver) = root.driver_name() {
-            println!("{}  Driver: {}", spaces, driver);
+
+    // Children have to be removed first
+    pub(super) fn remove(&mut self, id: DeviceId) -> Option<Device> {
+        let device = self.devices.remove(&id)?;
+        if let Some(parent) = device.parent.and_then(|parent| self.devices.get_mut(&parent)) {
+            parent.children.retain(|child| *child != id);
         }
-        
-        println!("{}  State: {:?}", spaces, root.state());
-        
-        for child in root.children() {
-            Self::print_tree(&child, indent + 2);
+        Some(device)
+    }
+
+    pub(super) fn set_binding(&mut self, id: DeviceId, driver: Option<&'static str>, state: DeviceState) {
+        if let Some(device) = self.devices.get_mut(&id) {
+            device.driver = driver;
+            device.state = state;
         }
     }
-}
\ No newline at end of file
+}
+
+/// Every device the kernel knows of
+pub static DEVICES: Mutex<DeviceTree> = Mutex::new(DeviceTree::new());
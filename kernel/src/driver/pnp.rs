@@ -0,0 +1,737 @@
+//! Plug and Play: device resources, removal, and configuration
+//!
+//! A device's resources are arbitrated before it is first probed. The I/O
+//! ports, memory ranges and IRQs it decodes are checked against every other
+//! device's, and kept where firmware left them if nothing else has them. A
+//! PCI BAR that collides, or that firmware left unassigned, is given the
+//! lowest free, aligned place in the window of the bridge above it. When
+//! there is none, the arbiter rebalances: the relocatable resources of the
+//! devices that can give them up are laid out again, the largest first, with
+//! the new device's. Those are the unbound devices, and the bound ones whose
+//! driver agrees to be stopped; the drivers of devices whose BARs move are
+//! unbound first and probed again after. A device whose resources cannot be
+//! found fails with "resource conflict".
+//!
+//! A removal is asked for first: every driver below the device can veto it,
+//! and when one does, those that had agreed are told it was cancelled. A
+//! surprise removal, of hardware that is already gone, is only announced to
+//! the drivers before they are unbound.
+//!
+//! Each device has a key under HKLM\SYSTEM\CurrentControlSet\Enum\<BUS>,
+//! with its driver (`Service`), `State` and `Resources`. `ConfigFlags` is
+//! read back from there: a device disabled there is neither given resources
+//! nor probed, for as long as the registry keeps it.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+
+use super::model::{Device, DeviceId, DeviceState, DeviceTree, Ident, DEVICES};
+use super::probe::{self, Driver};
+use crate::pcie::{
+    PciLocation, PcieController, PCIE_CONTROLLER, PCI_BAR0, PCI_COMMAND, PCI_COMMAND_IO, PCI_COMMAND_MASTER,
+    PCI_COMMAND_MEMORY, PCI_HEADER_TYPE, PCI_HEADER_TYPE_BRIDGE, PCI_HEADER_TYPE_NORMAL, PCI_INTERRUPT_LINE,
+    PCI_INTERRUPT_PIN,
+};
+use crate::nt::registry::{RegistryValue, REGISTRY_MANAGER};
+use crate::serial_println;
+
+/// The ConfigFlags bit of a disabled device, as Windows has it
+pub const CONFIGFLAG_DISABLED: u32 = 0x1;
+
+const ENUM_KEY: &str = "HKLM\\SYSTEM\\CurrentControlSet\\Enum";
+
+// Where BARs on the host bus may be moved to: the I/O ports above the ISA
+// ones, and the memory below 4 GiB between RAM and the I/O APIC. 64-bit BARs
+// are kept there too, as nothing says what above 4 GiB is free of RAM.
+const HOST_PORT_WINDOW: (u64, u64) = (0x1000, 0xFFFF);
+const HOST_MEMORY_WINDOW: (u64, u64) = (0xC000_0000, 0xFEBF_FFFF);
+
+// A bridge's forwarding windows
+const PCI_IO_BASE: u8 = 0x1C;
+const PCI_IO_LIMIT: u8 = 0x1D;
+const PCI_MEMORY_BASE: u8 = 0x20;
+const PCI_MEMORY_LIMIT: u8 = 0x22;
+const PCI_PREF_MEMORY_BASE: u8 = 0x24;
+const PCI_PREF_MEMORY_LIMIT: u8 = 0x26;
+const PCI_PREF_BASE_UPPER32: u8 = 0x28;
+const PCI_PREF_LIMIT_UPPER32: u8 = 0x2C;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Port,
+    Memory,
+    Irq,
+}
+
+impl ResourceKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceKind::Port => "io",
+            ResourceKind::Memory => "mem",
+            ResourceKind::Irq => "irq",
+        }
+    }
+}
+
+/// A range of I/O ports or memory, or an IRQ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub start: u64,
+    /// 1 for an IRQ
+    pub length: u64,
+}
+
+impl Resource {
+    pub fn end(&self) -> u64 {
+        self.start + self.length - 1
+    }
+
+    fn overlaps(&self, other: &Resource) -> bool {
+        self.kind == other.kind && self.start <= other.end() && other.start <= self.end()
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ResourceKind::Irq => write!(f, "irq {}", self.start),
+            kind => write!(f, "{} {:#x}-{:#x}", kind.name(), self.start, self.end()),
+        }
+    }
+}
+
+// What moving a requirement reprograms
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// Decoded at a fixed place
+    Fixed,
+    PciBar { location: PciLocation, index: usize },
+}
+
+/// What a device needs of one resource
+#[derive(Debug, Clone, Copy)]
+pub struct Requirement {
+    pub kind: ResourceKind,
+    pub length: u64,
+    pub alignment: u64,
+    /// The lowest and highest address it may be given
+    pub window: (u64, u64),
+    /// Where it is now, which it keeps if it can
+    pub current: Option<u64>,
+    /// Whether other devices may have it too
+    pub shared: bool,
+    source: Source,
+}
+
+impl Requirement {
+    fn fixed(kind: ResourceKind, start: u64, length: u64, shared: bool) -> Self {
+        let window = (start, start + length - 1);
+        Self { kind, length, alignment: 1, window, current: Some(start), shared, source: Source::Fixed }
+    }
+
+    pub fn relocatable(&self) -> bool {
+        matches!(self.source, Source::PciBar { .. })
+    }
+}
+
+// A device's requirements and what each was given
+struct Allocation {
+    requirements: Vec<Requirement>,
+    assigned: Vec<Resource>,
+    // What a bridge forwards to the buses behind it, which only the devices
+    // there may use
+    windows: Vec<Resource>,
+}
+
+static ALLOCATIONS: Mutex<BTreeMap<DeviceId, Allocation>> = Mutex::new(BTreeMap::new());
+
+// Something a device may not take, unless both it and the claim are shared
+#[derive(Clone, Copy)]
+struct Claim {
+    resource: Resource,
+    shared: bool,
+}
+
+fn platform_requirements(name: &str, instance: u32) -> Vec<Requirement> {
+    use ResourceKind::{Irq, Memory, Port};
+
+    let fixed = Requirement::fixed;
+    match name {
+        "i8042" => {
+            let ports = [fixed(Port, 0x60, 1, false), fixed(Port, 0x64, 1, false)];
+            ports.into_iter().chain([fixed(Irq, 1, 1, false), fixed(Irq, 12, 1, false)]).collect()
+        }
+        "rtc_cmos" => vec![fixed(Port, 0x70, 2, false), fixed(Irq, 8, 1, false)],
+        "pcspkr" => vec![fixed(Port, 0x61, 1, false)],
+        "hpet" => vec![fixed(Memory, crate::time::hpet::base(), 0x400, false)],
+        // COM1 and COM3 share an IRQ, as COM2 and COM4 do
+        "serial8250" => match crate::serial::COM_PORTS.get(instance as usize) {
+            Some(&(base, irq)) => vec![fixed(Port, base as u64, 8, false), fixed(Irq, irq as u64, 1, true)],
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+// What a bridge forwards, each None when closed
+#[derive(Clone, Copy)]
+struct Windows {
+    port: Option<(u64, u64)>,
+    memory: Option<(u64, u64)>,
+    prefetchable: Option<(u64, u64)>,
+}
+
+const HOST_WINDOWS: Windows =
+    Windows { port: Some(HOST_PORT_WINDOW), memory: Some(HOST_MEMORY_WINDOW), prefetchable: None };
+
+impl Windows {
+    fn read(controller: &PcieController, bridge: PciLocation) -> Self {
+        let open = |(base, limit): (u64, u64)| (base <= limit).then_some((base, limit));
+        let io_base = (controller.read8(bridge, PCI_IO_BASE) as u64 & 0xF0) << 8;
+        let io_limit = ((controller.read8(bridge, PCI_IO_LIMIT) as u64 & 0xF0) << 8) | 0xFFF;
+        let memory_base = (controller.read16(bridge, PCI_MEMORY_BASE) as u64 & 0xFFF0) << 16;
+        let memory_limit = ((controller.read16(bridge, PCI_MEMORY_LIMIT) as u64 & 0xFFF0) << 16) | 0xF_FFFF;
+        let prefetchable_base = controller.read16(bridge, PCI_PREF_MEMORY_BASE) as u64;
+        let prefetchable_limit = controller.read16(bridge, PCI_PREF_MEMORY_LIMIT) as u64;
+        let mut prefetchable = ((prefetchable_base & 0xFFF0) << 16, ((prefetchable_limit & 0xFFF0) << 16) | 0xF_FFFF);
+        // The low bits say whether the upper halves are there
+        if prefetchable_base & 0xF == 1 {
+            prefetchable.0 |= (controller.read32(bridge, PCI_PREF_BASE_UPPER32) as u64) << 32;
+            prefetchable.1 |= (controller.read32(bridge, PCI_PREF_LIMIT_UPPER32) as u64) << 32;
+        }
+        Self {
+            port: open((io_base, io_limit)),
+            memory: open((memory_base, memory_limit)),
+            prefetchable: open(prefetchable),
+        }
+    }
+
+    fn for_bar(&self, kind: ResourceKind, prefetchable: bool) -> Option<(u64, u64)> {
+        match kind {
+            ResourceKind::Port => self.port,
+            _ if prefetchable => self.prefetchable.or(self.memory),
+            _ => self.memory,
+        }
+    }
+
+    fn resources(&self) -> Vec<Resource> {
+        let windows = [(ResourceKind::Port, self.port), (ResourceKind::Memory, self.memory)];
+        windows
+            .into_iter()
+            .chain([(ResourceKind::Memory, self.prefetchable)])
+            .filter_map(|(kind, window)| window.map(|(base, limit)| (kind, base, limit)))
+            .map(|(kind, base, limit)| Resource { kind, start: base, length: limit - base + 1 })
+            .collect()
+    }
+}
+
+// Size a BAR's register, leaving it as it was
+fn size_register(controller: &PcieController, location: PciLocation, register: u8) -> (u32, u32) {
+    let value = controller.read32(location, register);
+    controller.write32(location, register, 0xFFFF_FFFF);
+    let sized = controller.read32(location, register);
+    controller.write32(location, register, value);
+    (value, sized)
+}
+
+// A function's BARs and interrupt line, and if it is a bridge, its windows.
+// BARs firmware left unassigned where the bridge above has no window for
+// them are left out: nothing could reach them.
+fn pci_requirements(location: PciLocation, bridge: Option<PciLocation>) -> (Vec<Requirement>, Vec<Resource>) {
+    let controller = PCIE_CONTROLLER.lock();
+    let header = controller.read8(location, PCI_HEADER_TYPE) & 0x7F;
+    let count = match header {
+        PCI_HEADER_TYPE_NORMAL => 6,
+        PCI_HEADER_TYPE_BRIDGE => 2,
+        _ => 0,
+    };
+    let above = bridge.map_or(HOST_WINDOWS, |bridge| Windows::read(&controller, bridge));
+
+    // Decoding is off while the BARs are sized
+    let command = controller.read16(location, PCI_COMMAND);
+    controller.write16(location, PCI_COMMAND, command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+    let mut requirements = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let (value, sized) = size_register(&controller, location, PCI_BAR0 + index as u8 * 4);
+        let io = value & 1 == 1;
+        let wide = !io && value & 0x6 == 0x4;
+        let (mut address, mut mask) = match io {
+            // Some only decode 16 bits of port
+            true => ((value & !0x3) as u64, (sized & !0x3) as u64 | 0xFFFF_FFFF_FFFF_0000),
+            false => ((value & !0xF) as u64, (sized & !0xF) as u64 | 0xFFFF_FFFF_0000_0000),
+        };
+        if wide {
+            let (high, sized_high) = size_register(&controller, location, PCI_BAR0 + index as u8 * 4 + 4);
+            address |= (high as u64) << 32;
+            mask = (mask & 0xFFFF_FFFF) | (sized_high as u64) << 32;
+        }
+        let bar = index;
+        index += if wide { 2 } else { 1 };
+        let length = (!mask).wrapping_add(1);
+        if sized & !0xF == 0 || length == 0 {
+            continue;
+        }
+        let kind = if io { ResourceKind::Port } else { ResourceKind::Memory };
+        let window = above.for_bar(kind, !io && value & 0x8 != 0);
+        let current = (address != 0).then_some(address);
+        let window = match (window, current) {
+            (Some(window), _) => window,
+            (None, Some(current)) => (current, current + length - 1),
+            (None, None) => continue,
+        };
+        let source = Source::PciBar { location, index: bar };
+        requirements.push(Requirement { kind, length, alignment: length, window, current, shared: false, source });
+    }
+    controller.write16(location, PCI_COMMAND, command);
+
+    let (pin, line) = (controller.read8(location, PCI_INTERRUPT_PIN), controller.read8(location, PCI_INTERRUPT_LINE));
+    if pin != 0 && line != 0 && line != 0xFF {
+        requirements.push(Requirement::fixed(ResourceKind::Irq, line as u64, 1, true));
+    }
+    let windows = match header {
+        PCI_HEADER_TYPE_BRIDGE => Windows::read(&controller, location).resources(),
+        _ => Vec::new(),
+    };
+    (requirements, windows)
+}
+
+// What a device needs, and what it forwards if it is a bridge
+fn requirements(device: &Device) -> (Vec<Requirement>, Vec<Resource>) {
+    match device.ident {
+        Ident::Platform { name, instance } => (platform_requirements(name, instance), Vec::new()),
+        Ident::Pci { location, .. } => {
+            let parent = device.parent.and_then(|parent| DEVICES.lock().get(parent).map(|parent| parent.ident));
+            let bridge = match parent {
+                Some(Ident::Pci { location, .. }) => Some(location),
+                _ => None,
+            };
+            pci_requirements(location, bridge)
+        }
+        _ => (Vec::new(), Vec::new()),
+    }
+}
+
+fn ancestors(devices: &DeviceTree, id: DeviceId) -> Vec<DeviceId> {
+    let mut found = Vec::new();
+    let mut next = devices.get(id).and_then(|device| device.parent);
+    while let Some(parent) = next {
+        found.push(parent);
+        next = devices.get(parent).and_then(|device| device.parent);
+    }
+    found
+}
+
+// What `id` may not take: everything other devices were given, but the
+// relocatable resources of those being moved, and the windows of bridges
+// it is not behind
+fn claims(
+    allocations: &BTreeMap<DeviceId, Allocation>,
+    id: DeviceId,
+    ancestors: &[DeviceId],
+    moving: &[DeviceId],
+) -> Vec<Claim> {
+    let mut claims = Vec::new();
+    for (owner, allocation) in allocations.iter().filter(|(owner, _)| **owner != id) {
+        for (requirement, resource) in allocation.requirements.iter().zip(&allocation.assigned) {
+            if !(moving.contains(owner) && requirement.relocatable()) {
+                claims.push(Claim { resource: *resource, shared: requirement.shared });
+            }
+        }
+        if !ancestors.contains(owner) {
+            claims.extend(allocation.windows.iter().map(|window| Claim { resource: *window, shared: false }));
+        }
+    }
+    claims
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+// Where a requirement can go: where it is now if that is free, and otherwise
+// the lowest aligned place in its window that is
+fn place(requirement: &Requirement, claims: &[Claim]) -> Option<u64> {
+    // The end of what is in the way at `start`, if anything is
+    let blocked = |start: u64| {
+        let resource = Resource { kind: requirement.kind, start, length: requirement.length };
+        claims
+            .iter()
+            .filter(|claim| claim.resource.overlaps(&resource) && !(requirement.shared && claim.shared))
+            .map(|claim| claim.resource.end())
+            .max()
+    };
+    if let Some(current) = requirement.current {
+        if blocked(current).is_none() {
+            return Some(current);
+        }
+    }
+    if !requirement.relocatable() {
+        return None;
+    }
+    let (low, high) = requirement.window;
+    let mut start = align_up(low, requirement.alignment);
+    while start.checked_add(requirement.length - 1)? <= high {
+        match blocked(start) {
+            None => return Some(start),
+            Some(end) => start = align_up(end.checked_add(1)?, requirement.alignment),
+        }
+    }
+    None
+}
+
+fn assign(requirements: &[Requirement], mut claims: Vec<Claim>) -> Option<Vec<Resource>> {
+    let mut assigned = Vec::new();
+    for requirement in requirements {
+        let start = place(requirement, &claims)?;
+        let resource = Resource { kind: requirement.kind, start, length: requirement.length };
+        claims.push(Claim { resource, shared: requirement.shared });
+        assigned.push(resource);
+    }
+    Some(assigned)
+}
+
+// Write the BARs that were given somewhere other than where they are
+fn program(requirements: &[Requirement], assigned: &[Resource]) {
+    for (requirement, resource) in requirements.iter().zip(assigned) {
+        if let Source::PciBar { location, index } = requirement.source {
+            if requirement.current != Some(resource.start) {
+                PCIE_CONTROLLER.lock().move_bar(location, index, resource.start);
+            }
+        }
+    }
+}
+
+// Give a device its resources, moving others' to make room if need be
+fn arbitrate(device: &Device) -> bool {
+    let (requirements, windows) = requirements(device);
+    if requirements.is_empty() && windows.is_empty() {
+        return true;
+    }
+    let ancestors = ancestors(&DEVICES.lock(), device.id);
+    let assigned = {
+        let mut allocations = ALLOCATIONS.lock();
+        let claims = claims(&allocations, device.id, &ancestors, &[]);
+        let assigned = assign(&requirements, claims);
+        if let Some(assigned) = &assigned {
+            let allocation =
+                Allocation { requirements: requirements.clone(), assigned: assigned.clone(), windows: windows.clone() };
+            allocations.insert(device.id, allocation);
+        }
+        assigned
+    };
+    match assigned {
+        Some(assigned) => {
+            program(&requirements, &assigned);
+            true
+        }
+        None => rebalance(device, requirements, windows),
+    }
+}
+
+// Whether a device's resources may be moved: it is not bound, or its driver
+// agrees to be stopped
+fn stoppable(device: &Device) -> bool {
+    match device.driver.and_then(probe::driver_named) {
+        Some(driver) => driver.query_stop(device).is_ok(),
+        None => true,
+    }
+}
+
+// Lay out the new device's resources and the relocatable ones of every
+// device that can be stopped, the fixed ones and then the largest first,
+// each kept where it is if it still fits
+fn rebalance(device: &Device, requirements: Vec<Requirement>, windows: Vec<Resource>) -> bool {
+    let relocatable: Vec<DeviceId> = ALLOCATIONS
+        .lock()
+        .iter()
+        .filter(|(_, allocation)| allocation.requirements.iter().any(Requirement::relocatable))
+        .map(|(owner, _)| *owner)
+        .collect();
+    let others: Vec<Device> = {
+        let devices = DEVICES.lock();
+        relocatable.iter().filter_map(|owner| devices.get(*owner).cloned()).collect()
+    };
+    let moving: Vec<DeviceId> = others.iter().filter(|other| stoppable(other)).map(|other| other.id).collect();
+    let ancestry: BTreeMap<DeviceId, Vec<DeviceId>> = {
+        let devices = DEVICES.lock();
+        moving.iter().chain([&device.id]).map(|id| (*id, ancestors(&devices, *id))).collect()
+    };
+
+    let mut allocations = ALLOCATIONS.lock();
+    // Each item is its owner, its index among the owner's requirements, and
+    // the requirement
+    let mut items: Vec<(DeviceId, usize, Requirement)> =
+        requirements.iter().enumerate().map(|(index, requirement)| (device.id, index, *requirement)).collect();
+    for owner in &moving {
+        let allocation = &allocations[owner];
+        for (index, requirement) in allocation.requirements.iter().enumerate().filter(|(_, r)| r.relocatable()) {
+            // Where it is now, which may not be where firmware put it
+            let current = Some(allocation.assigned[index].start);
+            items.push((*owner, index, Requirement { current, ..*requirement }));
+        }
+    }
+    items.sort_by_key(|(_, _, requirement)| (requirement.relocatable(), core::cmp::Reverse(requirement.length)));
+
+    let mut placed: Vec<(DeviceId, usize, Resource, bool)> = Vec::new();
+    for (owner, index, requirement) in &items {
+        let mut claims = claims(&allocations, *owner, &ancestry[owner], &moving);
+        // The owner's own fixed resources still stand
+        if let Some(allocation) = allocations.get(owner) {
+            for (requirement, resource) in allocation.requirements.iter().zip(&allocation.assigned) {
+                if !requirement.relocatable() {
+                    claims.push(Claim { resource: *resource, shared: requirement.shared });
+                }
+            }
+        }
+        claims.extend(placed.iter().map(|(_, _, resource, shared)| Claim { resource: *resource, shared: *shared }));
+        let Some(start) = place(requirement, &claims) else {
+            let kind = requirement.kind.name();
+            serial_println!("pnp: no room for {} of {:#x} for {}", kind, requirement.length, device.name);
+            return false;
+        };
+        let resource = Resource { kind: requirement.kind, start, length: requirement.length };
+        placed.push((*owner, *index, resource, requirement.shared));
+    }
+
+    // The devices whose BARs move, which are stopped while they do
+    let moved: Vec<DeviceId> = moving
+        .iter()
+        .copied()
+        .filter(|owner| {
+            let allocation = &allocations[owner];
+            placed.iter().any(|(placed_owner, index, resource, _)| {
+                placed_owner == owner && allocation.assigned[*index].start != resource.start
+            })
+        })
+        .collect();
+    let assigned: Vec<Resource> = (0..requirements.len())
+        .map(|index| placed.iter().find(|(owner, i, _, _)| *owner == device.id && *i == index).unwrap().2)
+        .collect();
+    let allocation = Allocation { requirements: requirements.clone(), assigned: assigned.clone(), windows };
+    allocations.insert(device.id, allocation);
+    drop(allocations);
+
+    let rebind: Vec<DeviceId> = others
+        .iter()
+        .filter(|other| moved.contains(&other.id) && other.state == DeviceState::Bound)
+        .map(|other| other.id)
+        .collect();
+    for id in &rebind {
+        probe::unbind(*id);
+    }
+    for owner in &moved {
+        let (requirements, assigned) = {
+            let mut allocations = ALLOCATIONS.lock();
+            let allocation = allocations.get_mut(owner).unwrap();
+            let old = allocation.assigned.clone();
+            for (placed_owner, index, resource, _) in &placed {
+                if placed_owner == owner {
+                    allocation.assigned[*index] = *resource;
+                }
+            }
+            // Programmed from where the BARs were, not where firmware put them
+            let requirements: Vec<Requirement> = allocation
+                .requirements
+                .iter()
+                .zip(&old)
+                .map(|(requirement, resource)| Requirement { current: Some(resource.start), ..*requirement })
+                .collect();
+            (requirements, allocation.assigned.clone())
+        };
+        program(&requirements, &assigned);
+    }
+    program(&requirements, &assigned);
+    serial_println!("pnp: moved the resources of {} devices to make room for {}", moved.len(), device.name);
+    for id in rebind {
+        probe::probe_device(id);
+    }
+    true
+}
+
+/// The resources a device was given
+pub fn resources(id: DeviceId) -> Vec<Resource> {
+    ALLOCATIONS.lock().get(&id).map(|allocation| allocation.assigned.clone()).unwrap_or_default()
+}
+
+// HKLM\SYSTEM\CurrentControlSet\Enum\<BUS>\<name>
+fn key_path(device: &Device) -> String {
+    format!("{}\\{}\\{}", ENUM_KEY, device.bus.name().to_uppercase(), device.name)
+}
+
+fn config_flags(device: &Device) -> u32 {
+    REGISTRY_MANAGER
+        .lock()
+        .get_key_by_path(&key_path(device))
+        .and_then(|key| key.get_value("ConfigFlags"))
+        .and_then(RegistryValue::as_dword)
+        .unwrap_or(0)
+}
+
+fn set_config_flags(device: &Device, flags: u32) {
+    if let Some(key) = REGISTRY_MANAGER.lock().create_key_by_path(&key_path(device)) {
+        key.set_value("ConfigFlags".to_string(), RegistryValue::new_dword("ConfigFlags".to_string(), flags));
+    }
+}
+
+/// Write a device's driver, state and resources to its registry key
+pub(super) fn record(id: DeviceId) {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return;
+    };
+    if device.ident == Ident::Root {
+        return;
+    }
+    let resources: Vec<String> = resources(id).iter().map(|resource| resource.to_string()).collect();
+    let mut registry = REGISTRY_MANAGER.lock();
+    let Some(key) = registry.create_key_by_path(&key_path(&device)) else {
+        return;
+    };
+    let string = |name: &str, value: String| RegistryValue::new_string(name.to_string(), value);
+    key.set_value("Service".to_string(), string("Service", device.driver.unwrap_or("").to_string()));
+    key.set_value("State".to_string(), string("State", device.state.name().to_string()));
+    key.set_value("Resources".to_string(), string("Resources", resources.join(", ")));
+    if key.get_value("ConfigFlags").is_none() {
+        key.set_value("ConfigFlags".to_string(), RegistryValue::new_dword("ConfigFlags".to_string(), 0));
+    }
+}
+
+// Stop a PCI function decoding the resources it no longer has
+fn quiesce(device: &Device) {
+    if let Ident::Pci { location, .. } = device.ident {
+        let controller = PCIE_CONTROLLER.lock();
+        let command = controller.read16(location, PCI_COMMAND);
+        let decoding = PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER;
+        controller.write16(location, PCI_COMMAND, command & !decoding);
+    }
+}
+
+/// Before a device is first probed, leave it disabled if the registry says
+/// so and otherwise give it its resources. Returns whether to probe it.
+pub(super) fn configure(id: DeviceId) -> bool {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return false;
+    };
+    if device.ident == Ident::Root {
+        return true;
+    }
+    let state = if config_flags(&device) & CONFIGFLAG_DISABLED != 0 {
+        serial_println!("pnp: {} is disabled", device.name);
+        quiesce(&device);
+        DeviceState::Disabled
+    } else if arbitrate(&device) {
+        return true;
+    } else {
+        serial_println!("pnp: cannot give {} its resources", device.name);
+        DeviceState::Failed("resource conflict")
+    };
+    DEVICES.lock().set_binding(id, None, state);
+    record(id);
+    false
+}
+
+/// Take back a device's resources as it leaves the tree
+pub(super) fn release(id: DeviceId) {
+    ALLOCATIONS.lock().remove(&id);
+    record(id);
+}
+
+// A device and everything below it, children first
+fn subtree(id: DeviceId) -> Vec<Device> {
+    fn walk(devices: &DeviceTree, id: DeviceId, found: &mut Vec<Device>) {
+        for child in devices.children(id) {
+            walk(devices, child.id, found);
+        }
+        found.extend(devices.get(id).cloned());
+    }
+    let mut found = Vec::new();
+    walk(&DEVICES.lock(), id, &mut found);
+    found
+}
+
+/// Ask every driver below the devices whether they may go, and take them
+/// out of the tree if all agree. One veto keeps them all, and the drivers
+/// that had agreed are told the removal was cancelled.
+pub fn query_remove(ids: &[DeviceId]) -> Result<(), String> {
+    let mut devices: Vec<Device> = Vec::new();
+    for id in ids {
+        for device in subtree(*id) {
+            if !devices.iter().any(|found| found.id == device.id) {
+                devices.push(device);
+            }
+        }
+    }
+    let mut agreed: Vec<(&'static dyn Driver, &Device)> = Vec::new();
+    for device in &devices {
+        let Some(driver) = device.driver.and_then(probe::driver_named) else {
+            continue;
+        };
+        if let Err(reason) = driver.query_remove(device) {
+            for (driver, device) in agreed.into_iter().rev() {
+                driver.cancel_remove(device);
+            }
+            let path = DEVICES.lock().path(device.id);
+            serial_println!("pnp: {} vetoed the removal of {}: {}", driver.name(), path, reason);
+            return Err(format!("{} ({}): {}", path, driver.name(), reason));
+        }
+        agreed.push((driver, device));
+    }
+    for id in ids {
+        probe::unregister_device(*id);
+    }
+    Ok(())
+}
+
+/// Take a device that is already gone out of the tree, with everything
+/// below it, telling their drivers before they are unbound
+pub fn surprise_remove(id: DeviceId) {
+    for device in subtree(id) {
+        if let Some(driver) = device.driver.and_then(probe::driver_named) {
+            driver.surprise_removal(&device);
+        }
+    }
+    probe::unregister_device(id);
+}
+
+/// Disable a device, which unbinds it and gives up its resources, or enable
+/// it again. The setting is kept in the registry.
+pub fn set_enabled(id: DeviceId, enabled: bool) -> Result<(), String> {
+    let device = DEVICES.lock().get(id).cloned().ok_or("no such device")?;
+    if device.ident == Ident::Root {
+        return Err(String::from("a bus root cannot be disabled"));
+    }
+    let flags = config_flags(&device);
+    if enabled {
+        set_config_flags(&device, flags & !CONFIGFLAG_DISABLED);
+        if matches!(device.state, DeviceState::Disabled | DeviceState::Failed(_)) {
+            DEVICES.lock().set_binding(id, None, DeviceState::Unbound);
+            if configure(id) {
+                probe::probe_device(id);
+            }
+        }
+        return Ok(());
+    }
+    if device.state == DeviceState::Disabled {
+        return Ok(());
+    }
+    if !device.children.is_empty() {
+        return Err(String::from("devices below it would be cut off; remove it instead"));
+    }
+    if let Some(driver) = device.driver.and_then(probe::driver_named) {
+        driver.query_remove(&device).map_err(|reason| format!("{}: {}", driver.name(), reason))?;
+    }
+    probe::unbind(id);
+    quiesce(&device);
+    set_config_flags(&device, flags | CONFIGFLAG_DISABLED);
+    DEVICES.lock().set_binding(id, None, DeviceState::Disabled);
+    release(id);
+    serial_println!("pnp: {} disabled", device.name);
+    Ok(())
+}
@@ -1,408 +1,231 @@
-//! Device Probing and Driver Binding System
+//! Binding drivers to devices
 
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::String,
-    sync::Arc,
-    vec::Vec,
-};
-use core::{
-    sync::atomic::{AtomicU32, Ordering},
-};
-use spin::{Mutex, RwLock};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use super::{
-    Device, DeviceClass, DeviceId, Driver, DriverError, Result,
-    model::DeviceState,
-};
+use spin::Mutex;
+
+use super::model::{BusType, Device, DeviceId, DeviceState, Ident, Match, DEVICES};
+use super::pnp;
+use crate::power::device::DevicePowerState;
+use crate::serial_println;
 
-/// Driver probe result
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ProbeResult {
-    /// Driver successfully matched device
-    Success,
-    /// Driver cannot handle this device
-    Failed,
-    /// Probe deferred (dependencies not ready)
-    Deferred,
+pub enum ProbeError {
+    /// Something the driver needs is not there yet; try again later
+    Defer(&'static str),
+    /// Not a device this driver handles after all; the next match is tried
+    NoDevice,
+    Failed(&'static str),
 }
 
-/// Device matching criteria
-#[derive(Debug, Clone)]
-pub enum MatchCriteria {
-    /// Match by device class
-    Class(DeviceClass),
-    /// Match by vendor/device ID
-    VendorDevice { vendor: u16, device: u16 },
-    /// Match by compatible string
-    Compatible(String),
-    /// Match by device name pattern
-    NamePattern(String),
-    /// Custom match function
-    Custom(fn(&Device) -> bool),
-}
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
 
-/// Driver probe context
-pub struct ProbeContext {
-    /// Device being probed
-    pub device: Arc<Device>,
-    /// Probe attempt number
-    pub attempt: u32,
-    /// Probe flags
-    pub flags: ProbeFlags,
-}
+    fn bus(&self) -> BusType;
 
-/// Probe flags
-#[derive(Debug, Clone, Copy, Default)]
-pub struct ProbeFlags {
-    /// Force probe even if already bound
-    pub force: bool,
-    /// Probe asynchronously
-    pub async_probe: bool,
-    /// Allow partial binding
-    pub partial: bool,
-}
+    /// The devices the driver handles
+    fn id_table(&self) -> &'static [Match];
 
-/// Driver matching and probing system
-pub struct DriverProbe {
-    /// Match criteria for drivers
-    match_table: RwLock<BTreeMap<String, Vec<MatchCriteria>>>,
-    /// Deferred probe list
-    deferred_list: Mutex<Vec<DeferredProbe>>,
-    /// Probe statistics
-    stats: ProbeStats,
-}
+    /// Take charge of a device
+    fn probe(&self, device: &Device) -> Result<(), ProbeError>;
 
-/// Deferred probe entry
-struct DeferredProbe {
-    device: Arc<Device>,
-    driver: String,
-    attempts: u32,
-    reason: String,
-}
-
-/// Probe statistics
-struct ProbeStats {
-    total_probes: AtomicU32,
-    successful_probes: AtomicU32,
-    failed_probes: AtomicU32,
-    deferred_probes: AtomicU32,
-}
+    /// Let go of a device that is going away, or is being unbound
+    fn remove(&self, _device: &Device) {}
 
-impl DriverProbe {
-    /// Create new probe system
-    pub const fn new() -> Self {
-        Self {
-            match_table: RwLock::new(BTreeMap::new()),
-            deferred_list: Mutex::new(Vec::new()),
-            stats: ProbeStats {
-                total_probes: AtomicU32::new(0),
-                successful_probes: AtomicU32::new(0),
-                failed_probes: AtomicU32::new(0),
-                deferred_probes: AtomicU32::new(0),
-            },
-        }
-    }
-    
-    /// Register driver match criteria
-    pub fn register_match_criteria(
-        &self,
-        driver: String,
-        criteria: Vec<MatchCriteria>,
-    ) -> Result<()> {
-        self.match_table.write().insert(driver, criteria);
+    /// Whether the device may be removed; an error vetoes it. Nothing is let
+    /// go of yet: `remove` follows if every driver asked agrees, and
+    /// `cancel_remove` if one does not.
+    fn query_remove(&self, _device: &Device) -> Result<(), &'static str> {
         Ok(())
     }
-    
-    /// Match device to driver
-    pub fn match_device(&self, device: &Device, driver: &dyn Driver) -> bool {
-        let table = self.match_table.read();
-        
-        if let Some(criteria_list) = table.get(driver.name()) {
-            for criteria in criteria_list {
-                if self.check_match(device, criteria) {
-                    return true;
-                }
-            }
-        }
-        
-        // Fall back to driver's own matching
-        match driver.probe(device) {
-            ProbeResult::Success => true,
-            _ => false,
-        }
-    }
-    
-    /// Check if device matches criteria
-    fn check_match(&self, device: &Device, criteria: &MatchCriteria) -> bool {
-        match criteria {
-            MatchCriteria::Class(class) => device.class() == *class,
-            
-            MatchCriteria::VendorDevice { vendor, device: dev } => {
-                // Check vendor/device properties
-                if let Some(v) = device.get_property("vendor_id") {
-                    if let Some(d) = device.get_property("device_id") {
-                        // Would compare actual values
-                        return true;
-                    }
-                }
-                false
-            }
-            
-            MatchCriteria::Compatible(compat) => {
-                if let Some(prop) = device.get_property("compatible") {
-                    // Would check compatible string
-                    return true;
-                }
-                false
-            }
-            
-            MatchCriteria::NamePattern(pattern) => {
-                // Simple pattern matching (would use proper pattern matching)
-                device.name().contains(pattern)
-            }
-            
-            MatchCriteria::Custom(func) => func(device),
-        }
-    }
-    
-    /// Probe device with specific driver
-    pub fn probe_device_with_driver(
-        &self,
-        device: Arc<Device>,
-        driver: &dyn Driver,
-        flags: ProbeFlags,
-    ) -> ProbeResult {
-        self.stats.total_probes.fetch_add(1, Ordering::Relaxed);
-        
-        // Check if already bound and not forcing
-        if device.is_bound() && !flags.force {
-            return ProbeResult::Failed;
-        }
-        
-        // Set device state
-        device.set_state(DeviceState::Probing);
-        
-        // Create probe context
-        let context = ProbeContext {
-            device: device.clone(),
-            attempt: 1,
-            flags,
-        };
-        
-        // Call driver probe
-        let result = driver.probe(&device);
-        
-        match result {
-            ProbeResult::Success => {
-                // Bind driver
-                if let Err(_) = device.bind_driver(driver.name().into()) {
-                    device.set_state(DeviceState::Failed);
-                    self.stats.failed_probes.fetch_add(1, Ordering::Relaxed);
-                    return ProbeResult::Failed;
-                }
-                
-                // Attach driver
-                if let Err(_) = driver.attach(device.clone()) {
-                    let _ = device.unbind_driver();
-                    device.set_state(DeviceState::Failed);
-                    self.stats.failed_probes.fetch_add(1, Ordering::Relaxed);
-                    return ProbeResult::Failed;
-                }
-                
-                device.set_state(DeviceState::Active);
-                self.stats.successful_probes.fetch_add(1, Ordering::Relaxed);
-                ProbeResult::Success
-            }
-            
-            ProbeResult::Deferred => {
-                // Add to deferred list
-                self.defer_probe(device.clone(), driver.name().into(), "Dependencies not ready");
-                device.set_state(DeviceState::Uninitialized);
-                self.stats.deferred_probes.fetch_add(1, Ordering::Relaxed);
-                ProbeResult::Deferred
-            }
-            
-            ProbeResult::Failed => {
-                device.set_state(DeviceState::Uninitialized);
-                self.stats.failed_probes.fetch_add(1, Ordering::Relaxed);
-                ProbeResult::Failed
-            }
-        }
-    }
-    
-    /// Add device to deferred probe list
-    fn defer_probe(&self, device: Arc<Device>, driver: String, reason: &str) {
-        let mut deferred = self.deferred_list.lock();
-        
-        // Check if already deferred
-        for entry in deferred.iter_mut() {
-            if entry.device.id() == device.id() && entry.driver == driver {
-                entry.attempts += 1;
-                return;
-            }
-        }
-        
-        // Add new deferred entry
-        deferred.push(DeferredProbe {
-            device,
-            driver,
-            attempts: 1,
-            reason: reason.into(),
-        });
+
+    /// A removal `query_remove` agreed to is not happening after all
+    fn cancel_remove(&self, _device: &Device) {}
+
+    /// The hardware is already gone; `remove` follows, and must not wait on
+    /// it
+    fn surprise_removal(&self, _device: &Device) {}
+
+    /// Whether the device can be unbound for its resources to be moved, and
+    /// probed again after
+    fn query_stop(&self, _device: &Device) -> Result<(), &'static str> {
+        Err("driver cannot be stopped")
     }
-    
-    /// Retry deferred probes
-    pub fn retry_deferred_probes(&self) -> Result<()> {
-        let mut deferred = self.deferred_list.lock();
-        let mut completed = Vec::new();
-        
-        for (i, entry) in deferred.iter().enumerate() {
-            // Find driver
-            if let Some(driver) = self.find_driver(&entry.driver) {
-                let result = self.probe_device_with_driver(
-                    entry.device.clone(),
-                    &*driver,
-                    ProbeFlags::default(),
-                );
-                
-                if result == ProbeResult::Success {
-                    completed.push(i);
-                }
-            }
-        }
-        
-        // Remove successful probes
-        for i in completed.iter().rev() {
-            deferred.remove(*i);
-        }
-        
+
+    /// The device is leaving D0 for `state`, for a system suspend, shutdown,
+    /// or because it has been idle. With `wake`, it should stay able to wake
+    /// the system. An error keeps it in D0.
+    fn suspend(&self, _device: &Device, _state: DevicePowerState, _wake: bool) -> Result<(), &'static str> {
         Ok(())
     }
-    
-    /// Find driver by name
-    fn find_driver(&self, name: &str) -> Option<Arc<dyn Driver>> {
-        // Would look up in driver registry
-        None
-    }
-    
-    /// Get probe statistics
-    pub fn statistics(&self) -> ProbeStatistics {
-        ProbeStatistics {
-            total_probes: self.stats.total_probes.load(Ordering::Relaxed),
-            successful_probes: self.stats.successful_probes.load(Ordering::Relaxed),
-            failed_probes: self.stats.failed_probes.load(Ordering::Relaxed),
-            deferred_probes: self.stats.deferred_probes.load(Ordering::Relaxed),
-            deferred_count: self.deferred_list.lock().len() as u32,
-        }
+
+    /// The device is back in D0, its PCI configuration restored. An error
+    /// has it unbound and probed again.
+    fn resume(&self, _device: &Device) -> Result<(), &'static str> {
+        Ok(())
     }
 }
 
-/// Probe statistics
-#[derive(Debug, Clone, Copy)]
-pub struct ProbeStatistics {
-    pub total_probes: u32,
-    pub successful_probes: u32,
-    pub failed_probes: u32,
-    pub deferred_probes: u32,
-    pub deferred_count: u32,
+static DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+
+// Set while deferred devices are being retried, and a bind during the retry
+// asks for another pass
+static RETRYING: AtomicBool = AtomicBool::new(false);
+static RETRY_AGAIN: AtomicBool = AtomicBool::new(false);
+
+/// Register a driver and probe the devices already there that it matches
+pub fn register_driver(driver: &'static dyn Driver) {
+    DRIVERS.lock().push(driver);
+    let waiting: Vec<DeviceId> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.bus == driver.bus())
+        .filter(|device| matches!(device.state, DeviceState::Unbound | DeviceState::Deferred(_)))
+        .filter(|device| driver.id_table().iter().any(|entry| entry.matches(&device.ident)))
+        .map(|device| device.id)
+        .collect();
+    for id in waiting {
+        probe_device(id);
+    }
 }
 
-/// Global probe system
-static PROBE_SYSTEM: DriverProbe = DriverProbe::new();
-
-/// Get global probe system
-pub fn probe_system() -> &'static DriverProbe {
-    &PROBE_SYSTEM
+/// Registered drivers, in the order they were registered
+pub fn drivers() -> Vec<&'static dyn Driver> {
+    DRIVERS.lock().clone()
 }
 
-/// Device ID table for matching
-#[derive(Debug, Clone)]
-pub struct DeviceIdTable {
-    entries: Vec<DeviceIdEntry>,
+/// The registered driver with this name
+pub fn driver_named(name: &str) -> Option<&'static dyn Driver> {
+    DRIVERS.lock().iter().copied().find(|driver| driver.name() == name)
 }
 
-/// Device ID entry
-#[derive(Debug, Clone)]
-pub struct DeviceIdEntry {
-    pub vendor: Option<u16>,
-    pub device: Option<u16>,
-    pub subvendor: Option<u16>,
-    pub subdevice: Option<u16>,
-    pub class: Option<u32>,
-    pub class_mask: Option<u32>,
-    pub driver_data: usize,
+/// Add a device to the tree, give it its resources, and bind a driver to it
+/// if one matches
+pub fn register_device(
+    name: String,
+    bus: BusType,
+    ident: Ident,
+    parent: Option<DeviceId>,
+) -> Result<DeviceId, &'static str> {
+    let id = DEVICES.lock().insert(name, bus, ident, parent)?;
+    if pnp::configure(id) {
+        probe_device(id);
+    }
+    Ok(id)
 }
 
-impl DeviceIdTable {
-    /// Create new device ID table
-    pub fn new() -> Self {
-        Self {
-            entries: Vec::new(),
-        }
+/// Unbind a device and everything below it, the children first, and take
+/// them out of the tree
+pub fn unregister_device(id: DeviceId) {
+    let children = DEVICES.lock().get(id).map(|device| device.children.clone()).unwrap_or_default();
+    for child in children {
+        unregister_device(child);
     }
-    
-    /// Add entry to table
-    pub fn add_entry(mut self, entry: DeviceIdEntry) -> Self {
-        self.entries.push(entry);
-        self
+    unbind(id);
+    pnp::release(id);
+    crate::power::device::forget(id);
+    DEVICES.lock().remove(id);
+}
+
+/// Let a device's driver go of it
+pub fn unbind(id: DeviceId) {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return;
+    };
+    if let Some(driver) = device.driver.and_then(driver_named) {
+        driver.remove(&device);
+        serial_println!("driver: {} unbound from {}", driver.name(), device.name);
     }
-    
-    /// Match device against table
-    pub fn match_device(&self, device: &Device) -> Option<&DeviceIdEntry> {
-        for entry in &self.entries {
-            if self.match_entry(device, entry) {
-                return Some(entry);
-            }
-        }
-        None
+    DEVICES.lock().set_binding(id, None, DeviceState::Unbound);
+    pnp::record(id);
+}
+
+/// Try the drivers that match a device, in the order they were registered,
+/// until one binds it or asks to wait. Returns whether the device is bound.
+pub fn probe_device(id: DeviceId) -> bool {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return false;
+    };
+    match device.state {
+        DeviceState::Bound => return true,
+        DeviceState::Disabled => return false,
+        _ => {}
     }
-    
-    /// Check if device matches entry
-    fn match_entry(&self, device: &Device, entry: &DeviceIdEntry) -> bool {
-        // Check vendor
-        if let Some(vendor) = entry.vendor {
-            if let Some(dev_vendor) = device.get_property("vendor_id") {
-                // Would compare actual values
-            } else {
-                return false;
+    let candidates: Vec<&'static dyn Driver> = DRIVERS
+        .lock()
+        .iter()
+        .copied()
+        .filter(|driver| driver.bus() == device.bus)
+        .filter(|driver| driver.id_table().iter().any(|entry| entry.matches(&device.ident)))
+        .collect();
+
+    // The lock is not held across a probe, which may add devices of its own
+    let mut binding = (None, DeviceState::Unbound);
+    for driver in candidates {
+        match driver.probe(&device) {
+            Ok(()) => {
+                serial_println!("driver: {} bound to {}", driver.name(), device.name);
+                binding = (Some(driver.name()), DeviceState::Bound);
+                break;
             }
-        }
-        
-        // Check device
-        if let Some(dev_id) = entry.device {
-            if let Some(dev_device) = device.get_property("device_id") {
-                // Would compare actual values
-            } else {
-                return false;
+            Err(ProbeError::NoDevice) => continue,
+            Err(ProbeError::Defer(reason)) => {
+                if !matches!(device.state, DeviceState::Deferred(_)) {
+                    serial_println!("driver: {} deferred probe of {}: {}", driver.name(), device.name, reason);
+                }
+                binding = (None, DeviceState::Deferred(reason));
+                break;
             }
-        }
-        
-        // Check class
-        if let Some(class) = entry.class {
-            if let Some(mask) = entry.class_mask {
-                // Would check class with mask
+            Err(ProbeError::Failed(reason)) => {
+                serial_println!("driver: {} failed to probe {}: {}", driver.name(), device.name, reason);
+                binding = (None, DeviceState::Failed(reason));
+                break;
             }
         }
-        
-        true
     }
+    let bound = binding.1 == DeviceState::Bound;
+    DEVICES.lock().set_binding(id, binding.0, binding.1);
+    pnp::record(id);
+    if bound {
+        retry_deferred();
+    }
+    bound
 }
 
-/// Probe helper macros
-#[macro_export]
-macro_rules! device_id_table {
-    ( $( { $($field:ident : $value:expr),* } ),* ) => {
-        {
-            let mut table = DeviceIdTable::new();
-            $(
-                table = table.add_entry(DeviceIdEntry {
-                    $( $field: Some($value), )*
-                    ..Default::default()
-                });
-            )*
-            table
+/// Probe the deferred devices again, as long as each pass binds something
+pub fn retry_deferred() {
+    if RETRYING.swap(true, Ordering::AcqRel) {
+        RETRY_AGAIN.store(true, Ordering::Release);
+        return;
+    }
+    loop {
+        let deferred: Vec<DeviceId> = DEVICES
+            .lock()
+            .iter()
+            .filter(|device| matches!(device.state, DeviceState::Deferred(_)))
+            .map(|device| device.id)
+            .collect();
+        let mut progress = false;
+        for id in deferred {
+            progress |= probe_device(id);
+        }
+        if !progress && !RETRY_AGAIN.swap(false, Ordering::AcqRel) {
+            break;
         }
-    };
-}
\ No newline at end of file
+    }
+    RETRYING.store(false, Ordering::Release);
+}
+
+/// Devices still waiting, with what they wait for
+pub fn deferred() -> Vec<(String, &'static str)> {
+    let devices = DEVICES.lock();
+    devices
+        .iter()
+        .filter_map(|device| match device.state {
+            DeviceState::Deferred(reason) => Some((devices.path(device.id), reason)),
+            _ => None,
+        })
+        .collect()
+}
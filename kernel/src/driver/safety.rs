@@ -1,602 +0,0 @@
-//! Driver Safety and Verification Layer
-
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::String,
-    sync::Arc,
-    vec::Vec,
-};
-use core::{
-    mem,
-    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
-};
-use spin::{Mutex, RwLock};
-
-use super::{Driver, Device, DeviceId, DriverError, Result};
-
-/// Driver signature for verification
-#[derive(Debug, Clone)]
-pub struct DriverSignature {
-    /// Driver hash
-    pub hash: [u8; 32],
-    /// Signature algorithm
-    pub algorithm: SignatureAlgorithm,
-    /// Signature data
-    pub signature: Vec<u8>,
-    /// Signer certificate
-    pub certificate: Vec<u8>,
-}
-
-/// Signature algorithm
-#[derive(Debug, Clone, Copy)]
-pub enum SignatureAlgorithm {
-    Ed25519,
-    RsaSha256,
-    EcdsaP256,
-}
-
-/// Driver isolation domain
-pub struct IsolationDomain {
-    /// Domain ID
-    id: u32,
-    /// Domain name
-    name: String,
-    /// Memory limit
-    memory_limit: usize,
-    /// CPU quota
-    cpu_quota: u32,
-    /// Allowed capabilities
-    capabilities: DriverCapabilities,
-    /// Sandboxed
-    sandboxed: bool,
-}
-
-/// Driver capabilities for sandboxing
-#[derive(Debug, Clone, Copy, Default)]
-pub struct DriverCapabilities {
-    /// Can access physical memory
-    pub physical_memory: bool,
-    /// Can perform DMA
-    pub dma_access: bool,
-    /// Can handle interrupts
-    pub interrupt_handling: bool,
-    /// Can access I/O ports
-    pub io_port_access: bool,
-    /// Can modify page tables
-    pub page_table_access: bool,
-    /// Can execute privileged instructions
-    pub privileged_exec: bool,
-    /// Can access other drivers
-    pub inter_driver_comm: bool,
-    /// Can access userspace
-    pub userspace_access: bool,
-}
-
-/// Driver fault information
-#[derive(Debug, Clone)]
-pub struct DriverFault {
-    /// Fault type
-    pub fault_type: FaultType,
-    /// Driver name
-    pub driver: String,
-    /// Device ID (if applicable)
-    pub device_id: Option<DeviceId>,
-    /// Fault address
-    pub address: Option<u64>,
-    /// Fault description
-    pub description: String,
-    /// Timestamp
-    pub timestamp: u64,
-}
-
-/// Fault type
-#[derive(Debug, Clone, Copy)]
-pub enum FaultType {
-    /// Invalid memory access
-    MemoryViolation,
-    /// DMA boundary violation
-    DmaViolation,
-    /// Invalid I/O access
-    IoViolation,
-    /// Stack overflow
-    StackOverflow,
-    /// Deadlock detected
-    Deadlock,
-    /// Resource leak
-    ResourceLeak,
-    /// Timeout
-    Timeout,
-    /// Panic/crash
-    Panic,
-}
-
-/// Driver verifier
-pub struct DriverVerifier {
-    /// Verification enabled
-    enabled: AtomicBool,
-    /// Trusted certificates
-    trusted_certs: RwLock<Vec<Certificate>>,
-    /// Verification cache
-    cache: RwLock<BTreeMap<String, VerificationResult>>,
-    /// Statistics
-    stats: VerifierStats,
-}
-
-/// Certificate for driver signing
-struct Certificate {
-    subject: String,
-    public_key: Vec<u8>,
-    valid_from: u64,
-    valid_to: u64,
-}
-
-/// Verification result
-#[derive(Debug, Clone)]
-struct VerificationResult {
-    verified: bool,
-    timestamp: u64,
-    details: String,
-}
-
-/// Verifier statistics
-struct VerifierStats {
-    drivers_verified: AtomicU64,
-    verification_failures: AtomicU64,
-    signatures_checked: AtomicU64,
-}
-
-impl DriverVerifier {
-    /// Create new verifier
-    pub const fn new() -> Self {
-        Self {
-            enabled: AtomicBool::new(true),
-            trusted_certs: RwLock::new(Vec::new()),
-            cache: RwLock::new(BTreeMap::new()),
-            stats: VerifierStats {
-                drivers_verified: AtomicU64::new(0),
-                verification_failures: AtomicU64::new(0),
-                signatures_checked: AtomicU64::new(0),
-            },
-        }
-    }
-    
-    /// Verify driver signature
-    pub fn verify_signature(&self, driver: &dyn Driver, signature: &DriverSignature) -> Result<()> {
-        if !self.enabled.load(Ordering::Acquire) {
-            return Ok(());
-        }
-        
-        self.stats.signatures_checked.fetch_add(1, Ordering::Relaxed);
-        
-        // Check cache
-        let cache = self.cache.read();
-        if let Some(result) = cache.get(driver.name()) {
-            if result.verified {
-                return Ok(());
-            } else {
-                return Err(DriverError::VerificationFailed);
-            }
-        }
-        drop(cache);
-        
-        // Verify signature
-        let verified = self.verify_signature_impl(signature)?;
-        
-        // Cache result
-        self.cache.write().insert(
-            driver.name().into(),
-            VerificationResult {
-                verified,
-                timestamp: self.current_time(),
-                details: if verified {
-                    "Signature valid".into()
-                } else {
-                    "Signature invalid".into()
-                },
-            },
-        );
-        
-        if verified {
-            self.stats.drivers_verified.fetch_add(1, Ordering::Relaxed);
-            Ok(())
-        } else {
-            self.stats.verification_failures.fetch_add(1, Ordering::Relaxed);
-            Err(DriverError::VerificationFailed)
-        }
-    }
-    
-    /// Verify signature implementation
-    fn verify_signature_impl(&self, signature: &DriverSignature) -> Result<bool> {
-        // Would perform actual cryptographic verification
-        // For now, return true for demonstration
-        Ok(true)
-    }
-    
-    fn current_time(&self) -> u64 {
-        0 // Would get actual time
-    }
-}
-
-/// Driver sandbox
-pub struct DriverSandbox {
-    /// Isolation domains
-    domains: RwLock<BTreeMap<u32, Arc<IsolationDomain>>>,
-    /// Driver to domain mapping
-    driver_domains: RwLock<BTreeMap<String, u32>>,
-    /// Fault handler
-    fault_handler: FaultHandler,
-    /// Next domain ID
-    next_domain_id: AtomicU32,
-}
-
-impl DriverSandbox {
-    /// Create new sandbox
-    pub const fn new() -> Self {
-        Self {
-            domains: RwLock::new(BTreeMap::new()),
-            driver_domains: RwLock::new(BTreeMap::new()),
-            fault_handler: FaultHandler::new(),
-            next_domain_id: AtomicU32::new(1),
-        }
-    }
-    
-    /// Create isolation domain
-    pub fn create_domain(
-        &self,
-        name: String,
-        memory_limit: usize,
-        capabilities: DriverCapabilities,
-    ) -> Result<u32> {
-        let id = self.next_domain_id.fetch_add(1, Ordering::Relaxed);
-        
-        let domain = Arc::new(IsolationDomain {
-            id,
-            name: name.clone(),
-            memory_limit,
-            cpu_quota: 100, // Default quota
-            capabilities,
-            sandboxed: true,
-        });
-        
-        self.domains.write().insert(id, domain);
-        
-        Ok(id)
-    }
-    
-    /// Assign driver to domain
-    pub fn assign_driver(&self, driver: &dyn Driver, domain_id: u32) -> Result<()> {
-        let domains = self.domains.read();
-        
-        if !domains.contains_key(&domain_id) {
-            return Err(DriverError::NotFound);
-        }
-        
-        self.driver_domains.write().insert(driver.name().into(), domain_id);
-        
-        Ok(())
-    }
-    
-    /// Check capability
-    pub fn check_capability(&self, driver: &dyn Driver, capability: &str) -> bool {
-        let driver_domains = self.driver_domains.read();
-        
-        if let Some(domain_id) = driver_domains.get(driver.name()) {
-            let domains = self.domains.read();
-            
-            if let Some(domain) = domains.get(domain_id) {
-                match capability {
-                    "physical_memory" => domain.capabilities.physical_memory,
-                    "dma_access" => domain.capabilities.dma_access,
-                    "interrupt_handling" => domain.capabilities.interrupt_handling,
-                    "io_port_access" => domain.capabilities.io_port_access,
-                    _ => false,
-                }
-            } else {
-                false
-            }
-        } else {
-            true // Not sandboxed
-        }
-    }
-    
-    /// Handle driver fault
-    pub fn handle_fault(&self, fault: DriverFault) -> Result<()> {
-        self.fault_handler.handle_fault(fault)
-    }
-}
-
-/// Fault handler
-struct FaultHandler {
-    /// Fault log
-    faults: Mutex<Vec<DriverFault>>,
-    /// Fault counts per driver
-    fault_counts: RwLock<BTreeMap<String, u32>>,
-    /// Maximum faults before isolation
-    max_faults: AtomicU32,
-}
-
-impl FaultHandler {
-    const fn new() -> Self {
-        Self {
-            faults: Mutex::new(Vec::new()),
-            fault_counts: RwLock::new(BTreeMap::new()),
-            max_faults: AtomicU32::new(3),
-        }
-    }
-    
-    fn handle_fault(&self, fault: DriverFault) -> Result<()> {
-        // Log fault
-        self.faults.lock().push(fault.clone());
-        
-        // Update fault count
-        let mut counts = self.fault_counts.write();
-        let count = counts.entry(fault.driver.clone()).or_insert(0);
-        *count += 1;
-        
-        // Check if driver should be isolated
-        if *count >= self.max_faults.load(Ordering::Acquire) {
-            // Would isolate or unload driver
-            return Err(DriverError::AccessDenied);
-        }
-        
-        Ok(())
-    }
-}
-
-/// Static driver verification
-pub struct StaticVerifier {
-    /// Verification rules
-    rules: RwLock<Vec<VerificationRule>>,
-    /// Results cache
-    results: RwLock<BTreeMap<String, StaticVerificationResult>>,
-}
-
-/// Verification rule
-struct VerificationRule {
-    name: String,
-    check: Box<dyn Fn(&dyn Driver) -> bool + Send + Sync>,
-}
-
-/// Static verification result
-#[derive(Debug, Clone)]
-struct StaticVerificationResult {
-    passed: bool,
-    violations: Vec<String>,
-}
-
-impl StaticVerifier {
-    /// Create new static verifier
-    pub fn new() -> Self {
-        let mut verifier = Self {
-            rules: RwLock::new(Vec::new()),
-            results: RwLock::new(BTreeMap::new()),
-        };
-        
-        // Add default rules
-        verifier.add_default_rules();
-        
-        verifier
-    }
-    
-    /// Add default verification rules
-    fn add_default_rules(&mut self) {
-        // Rule: Driver must have name
-        self.add_rule(
-            "has_name".into(),
-            Box::new(|driver| !driver.name().is_empty()),
-        );
-        
-        // Rule: Driver must have version
-        self.add_rule(
-            "has_version".into(),
-            Box::new(|driver| driver.version() > 0),
-        );
-        
-        // More rules would be added
-    }
-    
-    /// Add verification rule
-    pub fn add_rule(&self, name: String, check: Box<dyn Fn(&dyn Driver) -> bool + Send + Sync>) {
-        self.rules.write().push(VerificationRule { name, check });
-    }
-    
-    /// Verify driver statically
-    pub fn verify(&self, driver: &dyn Driver) -> Result<()> {
-        let rules = self.rules.read();
-        let mut violations = Vec::new();
-        
-        for rule in rules.iter() {
-            if !(rule.check)(driver) {
-                violations.push(rule.name.clone());
-            }
-        }
-        
-        let result = StaticVerificationResult {
-            passed: violations.is_empty(),
-            violations: violations.clone(),
-        };
-        
-        self.results.write().insert(driver.name().into(), result);
-        
-        if violations.is_empty() {
-            Ok(())
-        } else {
-            Err(DriverError::VerificationFailed)
-        }
-    }
-}
-
-/// Runtime checker for driver behavior
-pub struct RuntimeChecker {
-    /// Checks enabled
-    enabled: AtomicBool,
-    /// Memory access tracking
-    memory_tracking: MemoryTracker,
-    /// Resource tracking
-    resource_tracking: ResourceTracker,
-    /// Deadlock detection
-    deadlock_detector: DeadlockDetector,
-}
-
-/// Memory access tracker
-struct MemoryTracker {
-    /// Valid memory ranges
-    valid_ranges: RwLock<Vec<MemoryRange>>,
-    /// Access violations
-    violations: AtomicU64,
-}
-
-/// Memory range
-struct MemoryRange {
-    start: u64,
-    end: u64,
-    writable: bool,
-}
-
-impl MemoryTracker {
-    fn check_access(&self, addr: u64, size: usize, write: bool) -> bool {
-        let ranges = self.valid_ranges.read();
-        
-        for range in ranges.iter() {
-            if addr >= range.start && addr + size as u64 <= range.end {
-                if !write || range.writable {
-                    return true;
-                }
-            }
-        }
-        
-        self.violations.fetch_add(1, Ordering::Relaxed);
-        false
-    }
-}
-
-/// Resource tracker
-struct ResourceTracker {
-    /// Allocated resources per driver
-    allocations: RwLock<BTreeMap<String, Vec<ResourceAllocation>>>,
-    /// Leak count
-    leaks: AtomicU64,
-}
-
-/// Resource allocation
-struct ResourceAllocation {
-    resource_type: ResourceType,
-    id: u64,
-    size: usize,
-}
-
-/// Resource type
-enum ResourceType {
-    Memory,
-    DmaBuffer,
-    Interrupt,
-    IoPort,
-}
-
-/// Deadlock detector
-struct DeadlockDetector {
-    /// Lock acquisition order
-    lock_order: RwLock<Vec<LockAcquisition>>,
-    /// Deadlocks detected
-    deadlocks: AtomicU64,
-}
-
-/// Lock acquisition record
-struct LockAcquisition {
-    driver: String,
-    lock_id: u64,
-    timestamp: u64,
-}
-
-impl RuntimeChecker {
-    /// Create new runtime checker
-    pub const fn new() -> Self {
-        Self {
-            enabled: AtomicBool::new(true),
-            memory_tracking: MemoryTracker {
-                valid_ranges: RwLock::new(Vec::new()),
-                violations: AtomicU64::new(0),
-            },
-            resource_tracking: ResourceTracker {
-                allocations: RwLock::new(BTreeMap::new()),
-                leaks: AtomicU64::new(0),
-            },
-            deadlock_detector: DeadlockDetector {
-                lock_order: RwLock::new(Vec::new()),
-                deadlocks: AtomicU64::new(0),
-            },
-        }
-    }
-    
-    /// Check memory access
-    pub fn check_memory_access(&self, addr: u64, size: usize, write: bool) -> bool {
-        if !self.enabled.load(Ordering::Acquire) {
-            return true;
-        }
-        
-        self.memory_tracking.check_access(addr, size, write)
-    }
-}
-
-/// Global safety manager
-pub struct SafetyManager {
-    /// Driver verifier
-    verifier: DriverVerifier,
-    /// Driver sandbox
-    sandbox: DriverSandbox,
-    /// Static verifier
-    static_verifier: StaticVerifier,
-    /// Runtime checker
-    runtime_checker: RuntimeChecker,
-}
-
-impl SafetyManager {
-    /// Create new safety manager
-    pub fn new() -> Self {
-        Self {
-            verifier: DriverVerifier::new(),
-            sandbox: DriverSandbox::new(),
-            static_verifier: StaticVerifier::new(),
-            runtime_checker: RuntimeChecker::new(),
-        }
-    }
-    
-    /// Verify driver completely
-    pub fn verify_driver(&self, driver: &dyn Driver) -> Result<()> {
-        // Static verification
-        self.static_verifier.verify(driver)?;
-        
-        // Would also check signature if provided
-        
-        Ok(())
-    }
-    
-    /// Create safe driver wrapper
-    pub fn wrap_driver(&self, driver: Arc<dyn Driver>) -> Arc<dyn Driver> {
-        // Would create a wrapper that enforces safety checks
-        driver
-    }
-}
-
-/// Global safety manager instance
-static SAFETY_MANAGER: Mutex<Option<SafetyManager>> = Mutex::new(None);
-
-/// Initialize safety manager
-pub fn init_safety() {
-    *SAFETY_MANAGER.lock() = Some(SafetyManager::new());
-}
-
-/// Get safety manager
-pub fn safety_manager() -> Option<SafetyManager> {
-    SAFETY_MANAGER.lock().take()
-}
-
-/// Verify driver
-pub fn verify_driver(driver: &dyn Driver) -> Result<()> {
-    if let Some(manager) = safety_manager() {
-        manager.verify_driver(driver)?;
-        *SAFETY_MANAGER.lock() = Some(manager);
-    }
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,174 @@
+//! The device tree as files, mounted on /sys
+//!
+//! `devices/` holds the tree itself, one directory per device, nested as the
+//! devices are. `bus/<bus>/devices/` has every device on a bus, and
+//! `bus/<bus>/drivers/<driver>/` the devices each driver has bound. Those
+//! lead to the same directories as under `devices/`. A device's directory
+//! has the files `bus`, `state`, `modalias`, `resources`, `power_state`,
+//! and `driver` while it is bound.
+//! Everything is built from the tree when it is read, and is read-only.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::model::{BusType, Device, DeviceState, Ident, DEVICES};
+use super::{pnp, probe};
+use crate::fs::{FileInfo, FileSystem, FileSystemError, FileType};
+
+enum Node {
+    // Entries, and whether each is a directory
+    Directory(Vec<(String, bool)>),
+    File(String),
+}
+
+pub struct SysFs;
+
+fn entries(names: impl Iterator<Item = String>) -> Node {
+    Node::Directory(names.map(|name| (name, true)).collect())
+}
+
+fn device_directory(device: &Device, children: Vec<String>) -> Node {
+    let mut entries: Vec<(String, bool)> =
+        ["bus", "state", "modalias", "resources", "power_state"].iter().map(|name| (name.to_string(), false)).collect();
+    if device.driver.is_some() {
+        entries.push((String::from("driver"), false));
+    }
+    entries.extend(children.into_iter().map(|name| (name, true)));
+    Node::Directory(entries)
+}
+
+fn attribute(device: &Device, name: &str) -> Option<Node> {
+    let text = match name {
+        "bus" => device.bus.name().to_string(),
+        "driver" => device.driver?.to_string(),
+        "modalias" => device.ident.modalias(),
+        "power_state" => crate::power::device::state(device.id).name().to_string(),
+        "resources" => {
+            let resources = pnp::resources(device.id);
+            resources.iter().map(|resource| resource.to_string()).collect::<Vec<String>>().join("\n")
+        }
+        "state" => match device.state {
+            DeviceState::Deferred(reason) | DeviceState::Failed(reason) => {
+                String::from(device.state.name()) + ": " + reason
+            }
+            state => state.name().to_string(),
+        },
+        _ => return None,
+    };
+    Some(Node::File(text + "\n"))
+}
+
+// A device's directory, or a file or child directory below it
+fn device_node(device: &Device, rest: &[&str]) -> Option<Node> {
+    let devices = DEVICES.lock();
+    let mut device = devices.get(device.id)?;
+    for (index, name) in rest.iter().enumerate() {
+        match devices.children(device.id).find(|child| child.name == *name) {
+            Some(child) => device = child,
+            None if index == rest.len() - 1 => return attribute(device, name),
+            None => return None,
+        }
+    }
+    let children = devices.children(device.id).map(|child| child.name.clone()).collect();
+    Some(device_directory(device, children))
+}
+
+fn on_bus(device: &Device, bus: BusType) -> bool {
+    device.bus == bus && device.ident != Ident::Root
+}
+
+fn lookup(path: &str) -> Result<Node, FileSystemError> {
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    let node = match components.as_slice() {
+        [] => Some(entries(["bus", "devices"].iter().map(|name| name.to_string()))),
+        ["devices"] => Some(entries(DEVICES.lock().roots().map(|device| device.name.clone()))),
+        ["devices", root, rest @ ..] => {
+            let device = DEVICES.lock().lookup(root).cloned();
+            device.and_then(|device| device_node(&device, rest))
+        }
+        ["bus"] => Some(entries(BusType::ALL.iter().map(|bus| bus.name().to_string()))),
+        ["bus", bus] => {
+            BusType::from_name(bus).map(|_| entries(["devices", "drivers"].iter().map(|name| name.to_string())))
+        }
+        ["bus", bus, "devices", rest @ ..] => BusType::from_name(bus).and_then(|bus| {
+            let members: Vec<Device> = DEVICES.lock().iter().filter(|device| on_bus(device, bus)).cloned().collect();
+            match rest {
+                [] => Some(entries(members.into_iter().map(|device| device.name))),
+                [name, rest @ ..] => device_node(members.iter().find(|device| device.name == *name)?, rest),
+            }
+        }),
+        ["bus", bus, "drivers", rest @ ..] => BusType::from_name(bus).and_then(|bus| {
+            let drivers = probe::drivers();
+            let mut drivers = drivers.iter().filter(|driver| driver.bus() == bus);
+            match rest {
+                [] => Some(entries(drivers.map(|driver| driver.name().to_string()))),
+                [driver, rest @ ..] => {
+                    let driver = drivers.find(|found| found.name() == *driver)?.name();
+                    let bound: Vec<Device> =
+                        DEVICES.lock().iter().filter(|device| device.driver == Some(driver)).cloned().collect();
+                    match rest {
+                        [] => Some(entries(bound.into_iter().map(|device| device.name))),
+                        [name, rest @ ..] => device_node(bound.iter().find(|device| device.name == *name)?, rest),
+                    }
+                }
+            }
+        }),
+        _ => None,
+    };
+    node.ok_or(FileSystemError::NotFound)
+}
+
+impl FileSystem for SysFs {
+    fn fs_type(&self) -> &'static str {
+        "sysfs"
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        match lookup(path)? {
+            Node::File(text) => Ok(text.into_bytes()),
+            Node::Directory(_) => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn write_file(&mut self, _path: &str, _data: &[u8]) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        match lookup(path)? {
+            Node::Directory(entries) => Ok(entries
+                .into_iter()
+                .map(|(name, directory)| {
+                    let (size, file_type, permissions) = if directory {
+                        (0, FileType::Directory, 0o555)
+                    } else {
+                        let size = match lookup(&(String::from(path) + "/" + &name)) {
+                            Ok(Node::File(text)) => text.len() as u64,
+                            _ => 0,
+                        };
+                        (size, FileType::Regular, 0o444)
+                    };
+                    FileInfo { name, size, file_type, permissions, modified: 0 }
+                })
+                .collect()),
+            Node::File(_) => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn delete(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError> {
+        let name = path.trim_end_matches('/').rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("/");
+        let (size, file_type, permissions) = match lookup(path)? {
+            Node::Directory(_) => (0, FileType::Directory, 0o555),
+            Node::File(text) => (text.len() as u64, FileType::Regular, 0o444),
+        };
+        Ok(FileInfo { name: name.to_string(), size, file_type, permissions, modified: 0 })
+    }
+}
@@ -0,0 +1,351 @@
+//! Block I/O scheduling
+//!
+//! Sector reads and writes go through a queue per disk rather than straight
+//! to its driver. Each request carries its process's I/O priority, and when
+//! several wait for one disk, realtime requests go first, then best-effort
+//! ones, each by level and then by arrival. A best-effort request gains a
+//! level for every 100 ms it waits, so that none starves behind a busier
+//! process. Idle-class requests go only once the disk has had nothing else
+//! to do for 100 ms.
+//!
+//! There is no I/O thread: a caller waits until its request is the one the
+//! queue picks, then drives the disk itself.
+//!
+//! A disk that has had nothing to do for a while can be spun down; see
+//! `standby_idle`. Its next request spins it up again.
+//!
+//! Each process is charged the bytes it read and wrote and the time its
+//! requests waited in the queue. Children inherit their parent's priority,
+//! as on Linux.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::disk::{DiskDriver, DiskError, DISK_MANAGER, SECTOR_SIZE};
+use crate::sync::Mutex;
+
+const NS_PER_MS: u64 = 1_000_000;
+// How long a disk must have been free of other work before idle-class
+// requests go, and how long a best-effort request waits per level it gains
+const IDLE_DELAY_NS: u64 = 100 * NS_PER_MS;
+const AGING_NS: u64 = 100 * NS_PER_MS;
+
+/// Levels run from 0, served first, to 7
+pub const IOPRIO_LEVELS: u8 = 8;
+
+// Linux's ioprio encoding: the class in the top three bits of 16
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+const IOPRIO_CLASS_RT: u16 = 1;
+const IOPRIO_CLASS_BE: u16 = 2;
+const IOPRIO_CLASS_IDLE: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoClass {
+    RealTime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPriority {
+    pub class: IoClass,
+    /// 0 to 7; ignored for the idle class
+    pub level: u8,
+}
+
+impl IoPriority {
+    pub const DEFAULT: IoPriority = IoPriority { class: IoClass::BestEffort, level: 4 };
+
+    pub fn new(class: IoClass, level: u8) -> Option<Self> {
+        (level < IOPRIO_LEVELS).then_some(IoPriority { class, level: if class == IoClass::Idle { 0 } else { level } })
+    }
+
+    /// From Linux's ioprio value; class 0 means the default
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        let level = (raw & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8;
+        match raw >> IOPRIO_CLASS_SHIFT {
+            0 => Some(Self::DEFAULT),
+            IOPRIO_CLASS_RT => Self::new(IoClass::RealTime, level),
+            IOPRIO_CLASS_BE => Self::new(IoClass::BestEffort, level),
+            IOPRIO_CLASS_IDLE => Self::new(IoClass::Idle, 0),
+            _ => None,
+        }
+    }
+
+    pub fn to_raw(self) -> u16 {
+        let class = match self.class {
+            IoClass::RealTime => IOPRIO_CLASS_RT,
+            IoClass::BestEffort => IOPRIO_CLASS_BE,
+            IoClass::Idle => IOPRIO_CLASS_IDLE,
+        };
+        class << IOPRIO_CLASS_SHIFT | self.level as u16
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.class {
+            IoClass::RealTime => write!(f, "rt/{}", self.level),
+            IoClass::BestEffort => write!(f, "be/{}", self.level),
+            IoClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Parses "rt/N", "be/N", "idle", or a class alone at level 4
+impl core::str::FromStr for IoPriority {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, ()> {
+        let (class, level) = match text.split_once('/') {
+            Some((class, level)) => (class, level.parse().map_err(|_| ())?),
+            None => (text, IoPriority::DEFAULT.level),
+        };
+        let class = match class {
+            "rt" | "realtime" => IoClass::RealTime,
+            "be" | "best-effort" => IoClass::BestEffort,
+            "idle" => IoClass::Idle,
+            _ => return Err(()),
+        };
+        IoPriority::new(class, level).ok_or(())
+    }
+}
+
+/// A process's I/O since it started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoStats {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub requests: u64,
+    /// Time its requests spent queued behind others
+    pub wait_ns: u64,
+}
+
+struct Request {
+    id: u64,
+    priority: IoPriority,
+    queued_ns: u64,
+}
+
+impl Request {
+    // Lower goes first
+    fn rank(&self, now: u64) -> (IoClass, u8, u64) {
+        let level = match self.priority.class {
+            IoClass::BestEffort => {
+                let aged = (now.saturating_sub(self.queued_ns) / AGING_NS).min(IOPRIO_LEVELS as u64) as u8;
+                self.priority.level.saturating_sub(aged)
+            }
+            _ => self.priority.level,
+        };
+        (self.priority.class, level, self.id)
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<Request>,
+    busy: bool,
+    // When a request other than an idle-class one last finished
+    last_active_ns: u64,
+    // When any request last finished
+    last_done_ns: u64,
+    // Spun down since its last request
+    standby: bool,
+    dispatched: [u64; 3],
+    max_wait_ns: u64,
+}
+
+impl Queue {
+    fn next(&self, now: u64) -> Option<u64> {
+        let best = self.pending.iter().min_by_key(|request| request.rank(now))?;
+        if best.priority.class == IoClass::Idle && now.saturating_sub(self.last_active_ns) < IDLE_DELAY_NS {
+            return None;
+        }
+        Some(best.id)
+    }
+}
+
+static QUEUES: Mutex<BTreeMap<usize, Queue>> = Mutex::new(BTreeMap::new());
+static PROCESSES: Mutex<BTreeMap<u32, (IoPriority, IoStats)>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// The process on whose behalf this I/O is done; 0 for the kernel, or when
+// the process table is busy
+fn current_pid() -> u32 {
+    crate::process::executor::EXECUTOR.try_lock().and_then(|executor| executor.get_current_pid()).unwrap_or(0)
+}
+
+pub fn priority(pid: u32) -> IoPriority {
+    PROCESSES.lock().get(&pid).map_or(IoPriority::DEFAULT, |(priority, _)| *priority)
+}
+
+pub fn set_priority(pid: u32, priority: IoPriority) {
+    PROCESSES.lock().entry(pid).or_insert((IoPriority::DEFAULT, IoStats::default())).0 = priority;
+}
+
+pub fn stats(pid: u32) -> IoStats {
+    PROCESSES.lock().get(&pid).map_or(IoStats::default(), |(_, stats)| *stats)
+}
+
+/// A new process starts with its parent's priority and no I/O of its own
+pub fn fork_process(parent: u32, child: u32) {
+    let priority = priority(parent);
+    if priority != IoPriority::DEFAULT {
+        PROCESSES.lock().insert(child, (priority, IoStats::default()));
+    }
+}
+
+pub fn release_process(pid: u32) {
+    PROCESSES.lock().remove(&pid);
+}
+
+// Wait for the disk's queue to pick a request for the calling process, run
+// `transfer` on the disk, and charge the process
+fn submit(
+    disk: usize,
+    bytes: u64,
+    write: bool,
+    transfer: impl FnOnce(&mut dyn DiskDriver) -> Result<(), DiskError>,
+) -> Result<(), DiskError> {
+    let pid = current_pid();
+    let priority = priority(pid);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let queued_ns = crate::time::monotonic_ns();
+    QUEUES.lock().entry(disk).or_default().pending.push(Request { id, priority, queued_ns });
+
+    let wait_ns = loop {
+        {
+            let mut queues = QUEUES.lock();
+            let queue = queues.get_mut(&disk).unwrap();
+            let now = crate::time::monotonic_ns();
+            if !queue.busy && queue.next(now) == Some(id) {
+                queue.pending.retain(|request| request.id != id);
+                queue.busy = true;
+                queue.dispatched[priority.class as usize] += 1;
+                let wait_ns = now.saturating_sub(queued_ns);
+                queue.max_wait_ns = queue.max_wait_ns.max(wait_ns);
+                break wait_ns;
+            }
+        }
+        core::hint::spin_loop();
+    };
+
+    let started_ns = crate::time::monotonic_ns();
+    let result = match DISK_MANAGER.lock().get_disk(disk) {
+        Some(driver) => transfer(driver.as_mut()),
+        None => Err(DiskError::NotFound),
+    };
+    let finished_ns = crate::time::monotonic_ns();
+
+    {
+        let mut queues = QUEUES.lock();
+        let queue = queues.get_mut(&disk).unwrap();
+        queue.busy = false;
+        queue.standby = false;
+        queue.last_done_ns = finished_ns;
+        if priority.class != IoClass::Idle {
+            queue.last_active_ns = finished_ns;
+        }
+    }
+    if result.is_ok() {
+        let mut processes = PROCESSES.lock();
+        let stats = &mut processes.entry(pid).or_insert((IoPriority::DEFAULT, IoStats::default())).1;
+        if write {
+            stats.write_bytes += bytes;
+        } else {
+            stats.read_bytes += bytes;
+        }
+        stats.requests += 1;
+        stats.wait_ns += wait_ns;
+        crate::monitoring::metrics::record_disk_io(!write, bytes, (finished_ns - started_ns) / 1000);
+    }
+    result
+}
+
+/// Read `count` sectors from `sector` of disk `disk`
+pub fn read(disk: usize, sector: u64, count: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+    let bytes = count as u64 * SECTOR_SIZE as u64;
+    submit(disk, bytes, false, |driver| driver.read_sectors(sector, count, buffer))
+}
+
+/// Write `count` sectors at `sector` of disk `disk`
+pub fn write(disk: usize, sector: u64, count: u32, data: &[u8]) -> Result<(), DiskError> {
+    let bytes = count as u64 * SECTOR_SIZE as u64;
+    submit(disk, bytes, true, |driver| driver.write_sectors(sector, count, data))
+}
+
+/// Spin down each disk that has had no requests for `timeout_ns`. Only
+/// disks read or written since boot are looked at.
+pub fn standby_idle(timeout_ns: u64) {
+    let now = crate::time::monotonic_ns();
+    let idle: Vec<usize> = QUEUES
+        .lock()
+        .iter_mut()
+        .filter(|(_, queue)| !queue.busy && !queue.standby && queue.pending.is_empty())
+        .filter(|(_, queue)| now.saturating_sub(queue.last_done_ns) >= timeout_ns)
+        .map(|(disk, queue)| {
+            // Held like a request, so none starts while the disk spins down
+            queue.busy = true;
+            *disk
+        })
+        .collect();
+    for disk in idle {
+        let result = match DISK_MANAGER.lock().get_disk(disk) {
+            Some(driver) => driver.standby(),
+            None => Err(DiskError::NotFound),
+        };
+        let mut queues = QUEUES.lock();
+        let queue = queues.get_mut(&disk).unwrap();
+        queue.busy = false;
+        match result {
+            Ok(()) => {
+                queue.standby = true;
+                crate::serial_println!("block: disk {} spun down", disk);
+            }
+            // Not asked again until it has been idle as long once more
+            Err(_) => queue.last_done_ns = crate::time::monotonic_ns(),
+        }
+    }
+}
+
+fn proc_io() -> String {
+    let mut text = format!("{:>5} {:<6} {:>12} {:>12} {:>8} {:>10}\n", "PID", "PRIO", "READ", "WRITTEN", "REQS", "WAIT_MS");
+    for (pid, (priority, stats)) in PROCESSES.lock().iter() {
+        text += &format!(
+            "{:>5} {:<6} {:>12} {:>12} {:>8} {:>10}\n",
+            pid,
+            format!("{}", priority),
+            stats.read_bytes,
+            stats.write_bytes,
+            stats.requests,
+            stats.wait_ns / NS_PER_MS
+        );
+    }
+    text
+}
+
+fn proc_queues() -> String {
+    let mut text = format!("{:>4} {:>7} {:>4} {:>10} {:>10} {:>10} {:>11}\n", "DISK", "PENDING", "BUSY", "RT", "BE", "IDLE", "MAX_WAIT_MS");
+    for (disk, queue) in QUEUES.lock().iter() {
+        text += &format!(
+            "{:>4} {:>7} {:>4} {:>10} {:>10} {:>10} {:>11}\n",
+            disk,
+            queue.pending.len(),
+            if queue.busy { "yes" } else { "no" },
+            queue.dispatched[IoClass::RealTime as usize],
+            queue.dispatched[IoClass::BestEffort as usize],
+            queue.dispatched[IoClass::Idle as usize],
+            queue.max_wait_ns / NS_PER_MS
+        );
+    }
+    text
+}
+
+pub fn init() {
+    crate::fs::procfs::register("block/io", proc_io);
+    crate::fs::procfs::register("block/queues", proc_queues);
+}
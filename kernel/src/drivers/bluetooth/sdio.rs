@@ -4,7 +4,7 @@ use spin::Mutex;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::bluetooth::{BluetoothAddress, BluetoothError};
-use crate::bluetooth::core::hci::HciTransport;
+use crate::bluetooth::hci::HciTransport;
 use super::{BluetoothDriver, DriverError};
 
 // SDIO function numbers for Bluetooth
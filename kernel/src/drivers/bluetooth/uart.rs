@@ -1,10 +1,14 @@
-use alloc::vec::Vec;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
 use spin::Mutex;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::bluetooth::{BluetoothAddress, BluetoothError};
-use crate::bluetooth::core::hci::HciTransport;
+use crate::bluetooth::hci::HciTransport;
+use crate::serial::{Owner, SerialPort};
+use crate::serial_println;
 use super::{BluetoothDriver, DriverError};
 
 // UART Bluetooth protocols
@@ -21,15 +25,17 @@ const H4_CMD: u8 = 0x01;
 const H4_ACL: u8 = 0x02;
 const H4_SCO: u8 = 0x03;
 const H4_EVT: u8 = 0x04;
-const H4_ISO: u8 = 0x05;
 
 // H5 packet types
-const H5_ACK: u8 = 0x00;
 const H5_HCI_CMD: u8 = 0x01;
 const H5_ACL_DATA: u8 = 0x02;
 const H5_SCO_DATA: u8 = 0x03;
-const H5_HCI_EVT: u8 = 0x04;
-const H5_LINK_CTL: u8 = 0x0F;
+
+// How long a packet may wait for room in the port's transmit ring
+const WRITE_TIMEOUT_MS: u64 = 1000;
+
+// Adapters on serial ports, by port index
+static ATTACHED: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
 
 pub struct UartBluetoothAdapter {
     port: u32,
@@ -37,14 +43,13 @@ pub struct UartBluetoothAdapter {
     protocol: UartProtocol,
     address: BluetoothAddress,
     initialized: AtomicBool,
+    // What has been read of the next packet, or packets
     rx_buffer: Mutex<Vec<u8>>,
-    tx_buffer: Mutex<Vec<u8>>,
     flow_control: bool,
-    
+
     // H5 specific
     h5_seq_tx: u8,
     h5_seq_rx: u8,
-    h5_ack_needed: bool,
 }
 
 impl UartBluetoothAdapter {
@@ -56,166 +61,121 @@ impl UartBluetoothAdapter {
             address: BluetoothAddress::new([0; 6]),
             initialized: AtomicBool::new(false),
             rx_buffer: Mutex::new(Vec::with_capacity(1024)),
-            tx_buffer: Mutex::new(Vec::with_capacity(1024)),
             flow_control: true,
             h5_seq_tx: 0,
             h5_seq_rx: 0,
-            h5_ack_needed: false,
         }
     }
 
-    fn configure_uart(&self) -> Result<(), DriverError> {
-        // Configure UART parameters
-        // This would interface with the UART driver
-        
-        // Set baudrate
-        self.set_baudrate(self.baudrate)?;
-        
-        // Set 8N1 (8 data bits, no parity, 1 stop bit)
-        self.set_line_control(8, 'N', 1)?;
-        
-        // Enable hardware flow control if supported
-        if self.flow_control {
-            self.enable_flow_control()?;
-        }
-        
-        Ok(())
-    }
-
-    fn set_baudrate(&self, baudrate: u32) -> Result<(), DriverError> {
-        // Set UART baudrate
-        // This would interface with the UART hardware
-        Ok(())
+    fn uart(&self) -> Result<&'static SerialPort, DriverError> {
+        crate::serial::port(self.port as usize).ok_or(DriverError::NotFound)
     }
 
-    fn set_line_control(&self, data_bits: u8, parity: char, 
-                       stop_bits: u8) -> Result<(), DriverError> {
-        // Set UART line control
-        // This would interface with the UART hardware
-        Ok(())
-    }
-
-    fn enable_flow_control(&self) -> Result<(), DriverError> {
-        // Enable RTS/CTS flow control
-        // This would interface with the UART hardware
-        Ok(())
-    }
-
-    fn uart_write(&self, data: &[u8]) -> Result<(), DriverError> {
-        // Write data to UART
-        // This would interface with the UART hardware
-        for byte in data {
-            self.uart_write_byte(*byte)?;
+    fn configure_uart(&self) -> Result<(), DriverError> {
+        // The UARTs always run 8N1
+        let uart = self.uart()?;
+        if self.baudrate == 0 || self.baudrate > crate::serial::uart::BASE_BAUD {
+            return Err(DriverError::Unsupported);
         }
+        uart.set_baud(self.baudrate);
+        // Controllers on a UART expect RTS/CTS
+        uart.set_flow_control(self.flow_control);
+        uart.flush_input();
         Ok(())
     }
 
-    fn uart_write_byte(&self, byte: u8) -> Result<(), DriverError> {
-        // Write single byte to UART
-        // This would interface with the UART hardware
-        Ok(())
-    }
-
-    fn uart_read(&self, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        // Read data from UART
-        // This would interface with the UART hardware
-        let mut count = 0;
-        while count < buffer.len() {
-            if let Some(byte) = self.uart_read_byte()? {
-                buffer[count] = byte;
-                count += 1;
-            } else {
-                break;
+    // Queue the whole of `data`, waiting for room if the ring is full
+    fn uart_write(&self, data: &[u8]) -> Result<(), DriverError> {
+        let uart = self.uart()?;
+        let deadline = crate::time::clocksource::now_ns() + WRITE_TIMEOUT_MS * 1_000_000;
+        let mut written = 0;
+        loop {
+            written += uart.write(&data[written..]);
+            if written == data.len() {
+                return Ok(());
             }
+            if crate::time::clocksource::now_ns() >= deadline {
+                return Err(DriverError::Timeout);
+            }
+            uart.poll();
+            core::hint::spin_loop();
         }
-        Ok(count)
     }
 
-    fn uart_read_byte(&self) -> Result<Option<u8>, DriverError> {
-        // Read single byte from UART
-        // This would interface with the UART hardware
-        Ok(None)
+    fn uart_read(&self, buffer: &mut [u8]) -> Result<usize, DriverError> {
+        let uart = self.uart()?;
+        uart.poll();
+        Ok(uart.read(buffer))
     }
 
     fn send_h4_packet(&self, packet_type: u8, data: &[u8]) -> Result<(), DriverError> {
-        // Send H4 packet
-        self.uart_write_byte(packet_type)?;
-        self.uart_write(data)?;
-        Ok(())
+        let mut packet = Vec::with_capacity(1 + data.len());
+        packet.push(packet_type);
+        packet.extend_from_slice(data);
+        self.uart_write(&packet)
     }
 
-    fn receive_h4_packet(&self, buffer: &mut [u8]) -> Result<(u8, usize), DriverError> {
-        // Receive H4 packet
-        let packet_type = self.uart_read_byte()?
-            .ok_or(DriverError::Timeout)?;
-        
-        let len = match packet_type {
-            H4_CMD => {
-                // Command packet: opcode (2) + length (1) + params
-                if buffer.len() < 3 {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[..3])?;
-                let param_len = buffer[2] as usize;
-                if buffer.len() < 3 + param_len {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[3..3 + param_len])?;
-                3 + param_len
-            },
-            H4_ACL => {
-                // ACL packet: handle (2) + length (2) + data
-                if buffer.len() < 4 {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[..4])?;
-                let data_len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
-                if buffer.len() < 4 + data_len {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[4..4 + data_len])?;
-                4 + data_len
-            },
-            H4_SCO => {
-                // SCO packet: handle (2) + length (1) + data
-                if buffer.len() < 3 {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[..3])?;
-                let data_len = buffer[2] as usize;
-                if buffer.len() < 3 + data_len {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[3..3 + data_len])?;
-                3 + data_len
-            },
-            H4_EVT => {
-                // Event packet: event (1) + length (1) + params
-                if buffer.len() < 2 {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[..2])?;
-                let param_len = buffer[1] as usize;
-                if buffer.len() < 2 + param_len {
-                    return Err(DriverError::InvalidResponse);
-                }
-                self.uart_read(&mut buffer[2..2 + param_len])?;
-                2 + param_len
-            },
-            _ => return Err(DriverError::InvalidResponse),
+    // Take one whole packet, with its indicator, if it has all come in.
+    // Bytes are kept until it has, so this never waits.
+    fn receive_h4_packet(&self, buffer: &mut [u8]) -> Result<usize, DriverError> {
+        let mut rx = self.rx_buffer.lock();
+        let mut chunk = [0u8; 256];
+        loop {
+            let count = self.uart_read(&mut chunk)?;
+            if count == 0 {
+                break;
+            }
+            rx.extend_from_slice(&chunk[..count]);
+        }
+
+        let Some(&packet_type) = rx.first() else {
+            return Ok(0);
         };
-        
-        Ok((packet_type, len))
+        // Header length, and where in the packet the length of the rest is
+        let header = match packet_type {
+            // Command packet: opcode (2) + length (1) + params
+            H4_CMD => 3,
+            // ACL packet: handle (2) + length (2) + data
+            H4_ACL => 4,
+            // SCO packet: handle (2) + length (1) + data
+            H4_SCO => 3,
+            // Event packet: event (1) + length (1) + params
+            H4_EVT => 2,
+            _ => {
+                // Out of step with the controller; start again from what
+                // comes next
+                rx.clear();
+                return Err(DriverError::InvalidResponse);
+            }
+        };
+        if rx.len() < 1 + header {
+            return Ok(0);
+        }
+        let body = match packet_type {
+            H4_ACL => u16::from_le_bytes([rx[3], rx[4]]) as usize,
+            _ => rx[header] as usize,
+        };
+        let len = 1 + header + body;
+        if rx.len() < len {
+            return Ok(0);
+        }
+        if buffer.len() < len {
+            rx.drain(..len);
+            return Err(DriverError::InvalidResponse);
+        }
+        buffer[..len].copy_from_slice(&rx[..len]);
+        rx.drain(..len);
+        Ok(len)
     }
 
-    fn send_h5_packet(&mut self, packet_type: u8, reliable: bool, 
+    fn send_h5_packet(&mut self, packet_type: u8, reliable: bool,
                      data: &[u8]) -> Result<(), DriverError> {
         // H5 three-wire protocol
         let mut packet = Vec::new();
-        
+
         // SLIP start
         packet.push(0xC0);
-        
+
         // Sequence numbers and flags
         let seq_byte = if reliable {
             (self.h5_seq_tx << 3) | (self.h5_seq_rx & 0x07) | 0x80
@@ -223,15 +183,15 @@ impl UartBluetoothAdapter {
             self.h5_seq_rx & 0x07
         };
         packet.push(seq_byte);
-        
+
         // Packet type and length
         packet.push((packet_type << 4) | ((data.len() >> 8) & 0x0F) as u8);
         packet.push((data.len() & 0xFF) as u8);
-        
+
         // Header checksum
         let header_crc = self.h5_crc(&packet[1..4]);
         packet.push(header_crc);
-        
+
         // Payload
         for byte in data {
             if *byte == 0xC0 || *byte == 0xDB {
@@ -241,21 +201,21 @@ impl UartBluetoothAdapter {
                 packet.push(*byte);
             }
         }
-        
+
         // Payload CRC
         let payload_crc = self.h5_crc(data);
         packet.push(payload_crc);
         packet.push(payload_crc >> 8);
-        
+
         // SLIP end
         packet.push(0xC0);
-        
+
         self.uart_write(&packet)?;
-        
+
         if reliable {
             self.h5_seq_tx = (self.h5_seq_tx + 1) & 0x07;
         }
-        
+
         Ok(())
     }
 
@@ -274,112 +234,37 @@ impl UartBluetoothAdapter {
         }
         !crc
     }
-
-    fn init_h5_link(&mut self) -> Result<(), DriverError> {
-        // Initialize H5 three-wire link
-        log::info!("Initializing H5 three-wire link");
-        
-        // Send SYNC message
-        let sync_msg = [0x01, 0x7E];
-        self.send_h5_packet(H5_LINK_CTL, false, &sync_msg)?;
-        
-        // Wait for SYNC_RESP
-        let mut buffer = [0u8; 256];
-        let _ = self.uart_read(&mut buffer)?;
-        
-        // Send CONFIG message
-        let config_msg = [0x03, 0xFC, 0x11];
-        self.send_h5_packet(H5_LINK_CTL, false, &config_msg)?;
-        
-        // Wait for CONFIG_RESP
-        let _ = self.uart_read(&mut buffer)?;
-        
-        Ok(())
-    }
-
-    fn change_baudrate(&mut self, new_baudrate: u32) -> Result<(), DriverError> {
-        // Send vendor-specific command to change controller baudrate
-        let mut cmd = vec![0x01, 0x18, 0xFC, 0x06];
-        cmd.extend_from_slice(&new_baudrate.to_le_bytes());
-        cmd.extend_from_slice(&[0x00, 0x00]);  // Flow control
-        
-        self.send_command(&cmd)?;
-        
-        // Wait for response
-        crate::time::sleep_ms(10);
-        
-        // Change host UART baudrate
-        self.baudrate = new_baudrate;
-        self.set_baudrate(new_baudrate)?;
-        
-        Ok(())
-    }
 }
 
 impl BluetoothDriver for UartBluetoothAdapter {
+    // Take the port and set it up. Resetting the controller and reading its
+    // address is left to the stack, which does it for every transport.
     fn init(&mut self) -> Result<(), DriverError> {
         if self.initialized.load(Ordering::SeqCst) {
             return Ok(());
         }
-
-        // Configure UART
-        self.configure_uart()?;
-        
-        // Initialize protocol
-        match self.protocol {
-            UartProtocol::H5 => {
-                self.init_h5_link()?;
-            },
-            _ => {}
-        }
-        
-        // Send reset command
-        self.reset()?;
-        
-        // Read local address
-        let addr_cmd = [0x01, 0x09, 0x10, 0x00];
-        self.send_command(&addr_cmd)?;
-        
-        let mut buffer = [0u8; 256];
-        let len = self.receive_data(&mut buffer)?;
-        
-        if len >= 10 && buffer[0] == 0x04 && buffer[1] == 0x0E {
-            if buffer[6] == 0x00 {
-                let mut addr = [0u8; 6];
-                addr.copy_from_slice(&buffer[7..13]);
-                self.address = BluetoothAddress::new(addr);
-            }
+        // H5's link establishment and retransmission are not done yet
+        if !matches!(self.protocol, UartProtocol::H4) {
+            return Err(DriverError::Unsupported);
         }
-        
-        // Change to higher baudrate if supported
-        if self.baudrate < 921600 {
-            if self.change_baudrate(921600).is_ok() {
-                log::info!("Changed UART baudrate to 921600");
-            }
+
+        self.uart()?.claim(Owner::Bluetooth).map_err(|_| DriverError::InitFailed)?;
+        if let Err(e) = self.configure_uart() {
+            self.uart()?.release(Owner::Bluetooth);
+            return Err(e);
         }
-        
-        // Load firmware if needed
-        self.load_firmware()?;
-        
+
         self.initialized.store(true, Ordering::SeqCst);
-        
-        log::info!("UART Bluetooth adapter initialized: {:?}", self.address);
-        
+
+        serial_println!("bluetooth: controller on ttyS{} at {} baud", self.port, self.baudrate);
+
         Ok(())
     }
 
     fn reset(&mut self) -> Result<(), DriverError> {
-        let reset_cmd = [0x01, 0x03, 0x0C, 0x00];
-        self.send_command(&reset_cmd)?;
-        
-        let mut buffer = [0u8; 256];
-        let len = self.receive_data(&mut buffer)?;
-        
-        if len < 6 || buffer[0] != 0x04 || buffer[1] != 0x0E {
-            return Err(DriverError::InvalidResponse);
-        }
-        
-        Ok(())
+        // HCI_Reset; its Command Complete comes back through receive_data
+        let reset_cmd = [0x03, 0x0C, 0x00];
+        self.send_command(&reset_cmd)
     }
 
     fn get_address(&self) -> BluetoothAddress {
@@ -410,26 +295,10 @@ impl BluetoothDriver for UartBluetoothAdapter {
         }
     }
 
+    // One whole packet behind its H4 indicator, or 0 if none has come in
     fn receive_data(&mut self, buffer: &mut [u8]) -> Result<usize, DriverError> {
         match self.protocol {
-            UartProtocol::H4 => {
-                let (packet_type, len) = self.receive_h4_packet(buffer)?;
-                // Prepend packet type for HCI processing
-                if len > 0 && buffer.len() > len {
-                    for i in (1..=len).rev() {
-                        buffer[i] = buffer[i - 1];
-                    }
-                    buffer[0] = packet_type;
-                    Ok(len + 1)
-                } else {
-                    Ok(len)
-                }
-            },
-            UartProtocol::H5 => {
-                // H5 packet reception would be more complex
-                // For now, simplified version
-                self.uart_read(buffer)
-            },
+            UartProtocol::H4 => self.receive_h4_packet(buffer),
             _ => Err(DriverError::Unsupported),
         }
     }
@@ -440,7 +309,7 @@ impl BluetoothDriver for UartBluetoothAdapter {
         Ok(())
     }
 
-    fn set_power(&mut self, on: bool) -> Result<(), DriverError> {
+    fn set_power(&mut self, _on: bool) -> Result<(), DriverError> {
         // Toggle power via GPIO if available
         // This would interface with GPIO subsystem
         Ok(())
@@ -454,9 +323,9 @@ impl HciTransport for UartBluetoothAdapter {
         }
 
         match data[0] {
-            0x01 => self.send_command(&data[1..]).map_err(|e| e.into()),
-            0x02 => self.send_acl_data(&data[1..]).map_err(|e| e.into()),
-            0x03 => self.send_sco_data(&data[1..]).map_err(|e| e.into()),
+            H4_CMD => self.send_command(&data[1..]).map_err(|e| e.into()),
+            H4_ACL => self.send_acl_data(&data[1..]).map_err(|e| e.into()),
+            H4_SCO => self.send_sco_data(&data[1..]).map_err(|e| e.into()),
             _ => Err(BluetoothError::InvalidParameter),
         }
     }
@@ -466,33 +335,36 @@ impl HciTransport for UartBluetoothAdapter {
     }
 }
 
-pub fn scan() -> Option<BluetoothAddress> {
-    // Scan UART ports for Bluetooth modules
-    // Common UART ports: /dev/ttyS0, /dev/ttyUSB0, /dev/ttyAMA0
-    
-    // Try common baudrates: 115200, 921600, 3000000
-    let baudrates = [115200, 921600, 3000000];
-    let ports = [0, 1, 2, 3];  // UART port numbers
-    
-    for port in ports {
-        for baudrate in baudrates {
-            let mut adapter = UartBluetoothAdapter::new(port, baudrate, UartProtocol::H4);
-            if adapter.init().is_ok() {
-                return Some(adapter.get_address());
+impl Drop for UartBluetoothAdapter {
+    fn drop(&mut self) {
+        if self.initialized.load(Ordering::SeqCst) {
+            if let Ok(uart) = self.uart() {
+                uart.release(Owner::Bluetooth);
             }
         }
     }
-    
-    None
 }
 
-pub fn probe_uart(port: u32, baudrate: u32, 
-                 protocol: UartProtocol) -> Option<Box<dyn BluetoothDriver>> {
-    let mut adapter = UartBluetoothAdapter::new(port, baudrate, protocol);
-    
-    if adapter.init().is_ok() {
-        Some(Box::new(adapter))
-    } else {
-        None
+/// Run a controller on a serial port, speaking H4, as an adapter. A
+/// controller cannot be found on a port by probing it, so this is asked for.
+pub fn attach(port: usize, baudrate: u32) -> Result<usize, BluetoothError> {
+    if ATTACHED.lock().contains_key(&port) {
+        return Err(BluetoothError::ResourceBusy);
     }
-}
\ No newline at end of file
+    let mut adapter = UartBluetoothAdapter::new(port as u32, baudrate, UartProtocol::H4);
+    adapter.init().map_err(|e| match e {
+        DriverError::InitFailed => BluetoothError::ResourceBusy,
+        e => e.into(),
+    })?;
+    let description = format!("ttyS{} at {} baud", port, baudrate);
+    let index = crate::bluetooth::add_adapter(Box::new(adapter), description);
+    ATTACHED.lock().insert(port, index);
+    Ok(index)
+}
+
+/// Stop using a serial port, dropping its adapter and giving the port back
+pub fn detach(port: usize) -> Result<(), BluetoothError> {
+    let index = ATTACHED.lock().remove(&port).ok_or(BluetoothError::NoAdapter)?;
+    crate::bluetooth::remove_adapter(index);
+    Ok(())
+}
@@ -1,352 +1,256 @@
-use alloc::vec::Vec;
-use alloc::boxed::Box;
-use spin::Mutex;
-use core::sync::atomic::{AtomicBool, Ordering};
+//! USB Bluetooth dongles
+//!
+//! A dongle's HCI interface, as the USB transport lays it out. Commands go
+//! as class requests on the default pipe, events come in on the interrupt
+//! endpoint, and ACL data goes both ways on the bulk pair. SCO, on the
+//! second interface's isochronous settings, is not used.
+//!
+//! Events and ACL data are read by URBs kept waiting, and put back into
+//! packets by their headers, since one may span several transfers. The
+//! packets wait in a queue until the stack's poll takes them. Dongles that
+//! want firmware loaded first, as many Intel and Realtek ones do, are run
+//! on the firmware in their ROM.
 
-use crate::bluetooth::{BluetoothAddress, BluetoothError};
-use crate::bluetooth::core::hci::HciTransport;
-use super::{BluetoothDriver, DriverError};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 
-// USB Bluetooth Class codes
-const USB_CLASS_WIRELESS: u8 = 0xE0;
-const USB_SUBCLASS_RF: u8 = 0x01;
-const USB_PROTOCOL_BLUETOOTH: u8 = 0x01;
+use spin::Mutex;
 
-// USB Bluetooth endpoints
-const EP_EVENTS: u8 = 0x81;    // Interrupt IN (HCI Events)
-const EP_ACL_IN: u8 = 0x82;    // Bulk IN (ACL Data)
-const EP_ACL_OUT: u8 = 0x02;   // Bulk OUT (ACL Data)
-const EP_SCO_IN: u8 = 0x83;    // Isochronous IN (SCO Data)
-const EP_SCO_OUT: u8 = 0x03;   // Isochronous OUT (SCO Data)
+use crate::bluetooth::hci::{HciTransport, PACKET_ACL, PACKET_COMMAND, PACKET_EVENT};
+use crate::bluetooth::BluetoothError;
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::serial_println;
+use crate::usb::urb::{self, Urb, UrbId, UrbStatus};
+use crate::usb::{DeviceRequest, USB_CLASS_VENDOR, USB_CLASS_WIRELESS};
+
+const SUBCLASS_RF: u8 = 0x01;
+const PROTOCOL_BLUETOOTH: u8 = 0x01;
+
+// Bulk IN transfers read this much at a time
+const ACL_TRANSFER_SIZE: usize = 1024;
+// How long a command or ACL packet may wait to be sent
+const SEND_TIMEOUT_MS: u64 = 5000;
+// Packets held for the stack before the oldest are dropped
+const MAX_QUEUED: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Events,
+    Acl,
+}
 
-pub struct UsbBluetoothAdapter {
-    device_id: u16,
-    vendor_id: u16,
-    product_id: u16,
-    address: BluetoothAddress,
-    initialized: AtomicBool,
-    rx_buffer: Mutex<Vec<u8>>,
-    tx_buffer: Mutex<Vec<u8>>,
+/// What has come in: whole packets, each behind its H4 type byte, and the
+/// start of the next one on each stream
+#[derive(Default)]
+struct Incoming {
+    packets: VecDeque<Vec<u8>>,
+    events: Vec<u8>,
+    acl: Vec<u8>,
 }
 
-impl UsbBluetoothAdapter {
-    pub fn new(vendor_id: u16, product_id: u16) -> Self {
-        Self {
-            device_id: 0,
-            vendor_id,
-            product_id,
-            address: BluetoothAddress::new([0; 6]),
-            initialized: AtomicBool::new(false),
-            rx_buffer: Mutex::new(Vec::with_capacity(1024)),
-            tx_buffer: Mutex::new(Vec::with_capacity(1024)),
+impl Incoming {
+    fn push(&mut self, stream: Stream, data: &[u8]) {
+        let (partial, kind) = match stream {
+            Stream::Events => (&mut self.events, PACKET_EVENT),
+            Stream::Acl => (&mut self.acl, PACKET_ACL),
+        };
+        partial.extend_from_slice(data);
+        loop {
+            // An event's length is a byte after its code, an ACL packet's
+            // two after its handle
+            let length = match stream {
+                Stream::Events if partial.len() >= 2 => 2 + partial[1] as usize,
+                Stream::Acl if partial.len() >= 4 => 4 + u16::from_le_bytes([partial[2], partial[3]]) as usize,
+                _ => break,
+            };
+            if partial.len() < length {
+                break;
+            }
+            let mut packet = Vec::with_capacity(1 + length);
+            packet.push(kind);
+            packet.extend(partial.drain(..length));
+            if self.packets.len() == MAX_QUEUED {
+                self.packets.pop_front();
+            }
+            self.packets.push_back(packet);
         }
     }
+}
 
-    fn send_usb_control(&self, request: u8, value: u16, 
-                       index: u16, data: &[u8]) -> Result<(), DriverError> {
-        // Send USB control transfer
-        // This would interface with the USB subsystem
-        Ok(())
-    }
-
-    fn read_usb_bulk(&self, endpoint: u8, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        // Read from USB bulk endpoint
-        // This would interface with the USB subsystem
-        Ok(0)
-    }
-
-    fn write_usb_bulk(&self, endpoint: u8, data: &[u8]) -> Result<(), DriverError> {
-        // Write to USB bulk endpoint
-        // This would interface with the USB subsystem
-        Ok(())
-    }
+struct UsbTransport {
+    address: u8,
+    acl_out: u8,
+    incoming: Arc<Mutex<Incoming>>,
+}
 
-    fn read_usb_interrupt(&self, endpoint: u8, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        // Read from USB interrupt endpoint
-        // This would interface with the USB subsystem
-        Ok(0)
+impl HciTransport for UsbTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), BluetoothError> {
+        let (&kind, packet) = data.split_first().ok_or(BluetoothError::InvalidParameter)?;
+        let transfer = match kind {
+            PACKET_COMMAND => {
+                // Host to device, class, device
+                let setup =
+                    DeviceRequest { request_type: 0x20, request: 0, value: 0, index: 0, length: packet.len() as u16 };
+                Urb::control(self.address, setup, packet.to_vec())
+            }
+            PACKET_ACL => Urb::bulk(self.address, self.acl_out, packet.to_vec()),
+            _ => return Err(BluetoothError::NotSupported),
+        };
+        let address = self.address;
+        let transfer = transfer.with_timeout(SEND_TIMEOUT_MS).on_complete(move |urb| {
+            if urb.status != UrbStatus::Completed {
+                serial_println!("bluetooth: packet to USB device {} failed: {:?}", address, urb.status);
+            }
+        });
+        urb::submit(transfer).map(|_| ()).map_err(|_| BluetoothError::IoError)
     }
 
-    fn write_usb_isoc(&self, endpoint: u8, data: &[u8]) -> Result<(), DriverError> {
-        // Write to USB isochronous endpoint
-        // This would interface with the USB subsystem
-        Ok(())
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, BluetoothError> {
+        let Some(packet) = self.incoming.lock().packets.pop_front() else {
+            return Ok(0);
+        };
+        let target = buffer.get_mut(..packet.len()).ok_or(BluetoothError::NoMemory)?;
+        target.copy_from_slice(&packet);
+        Ok(packet.len())
     }
+}
 
-    fn read_usb_isoc(&self, endpoint: u8, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        // Read from USB isochronous endpoint
-        // This would interface with the USB subsystem
-        Ok(0)
-    }
+struct Dongle {
+    adapter: usize,
+    event_urb: Option<UrbId>,
+    acl_urb: Option<UrbId>,
+}
 
-    fn read_local_address(&mut self) -> Result<(), DriverError> {
-        // Send HCI Read BD_ADDR command
-        let cmd = [0x01, 0x09, 0x10, 0x00];  // Read BD_ADDR
-        self.send_command(&cmd)?;
-        
-        // Wait for response
-        let mut buffer = [0u8; 256];
-        let len = self.receive_data(&mut buffer)?;
-        
-        if len >= 10 && buffer[0] == 0x04 && buffer[1] == 0x0E {
-            // Command Complete event
-            if buffer[6] == 0x00 {  // Success
-                let mut addr = [0u8; 6];
-                addr.copy_from_slice(&buffer[7..13]);
-                self.address = BluetoothAddress::new(addr);
-                return Ok(());
+static DONGLES: Mutex<BTreeMap<u8, Dongle>> = Mutex::new(BTreeMap::new());
+
+// Wait for more of a stream. Each transfer that comes back is queued and
+// the URB sent again; a failed one stops the stream.
+fn submit(
+    address: u8,
+    stream: Stream,
+    endpoint: u8,
+    size: usize,
+    incoming: Arc<Mutex<Incoming>>,
+) -> Result<UrbId, &'static str> {
+    let transfer = match stream {
+        Stream::Events => Urb::interrupt(address, endpoint, vec![0u8; size]),
+        Stream::Acl => Urb::bulk(address, endpoint, vec![0u8; size]),
+    };
+    urb::submit(transfer.on_complete(move |urb| match urb.status {
+        UrbStatus::Completed => {
+            incoming.lock().push(stream, urb.data());
+            let next = submit(address, stream, endpoint, size, incoming).ok();
+            if let Some(dongle) = DONGLES.lock().get_mut(&address) {
+                match stream {
+                    Stream::Events => dongle.event_urb = next,
+                    Stream::Acl => dongle.acl_urb = next,
+                }
             }
         }
-        
-        Err(DriverError::InvalidResponse)
-    }
+        UrbStatus::Cancelled => {}
+        status => serial_println!("bluetooth: {:?} from USB device {} stopped: {:?}", stream, address, status),
+    }))
 }
 
-impl BluetoothDriver for UsbBluetoothAdapter {
-    fn init(&mut self) -> Result<(), DriverError> {
-        if self.initialized.load(Ordering::SeqCst) {
-            return Ok(());
-        }
-
-        // Reset the device
-        self.send_usb_control(0x00, 0x00, 0x00, &[])?;
-        
-        // Set configuration
-        self.send_usb_control(0x09, 0x01, 0x00, &[])?;
-        
-        // Read local Bluetooth address
-        self.read_local_address()?;
-        
-        // Load firmware if needed
-        self.load_firmware()?;
-        
-        self.initialized.store(true, Ordering::SeqCst);
-        
-        log::info!("USB Bluetooth adapter initialized: {:?}", self.address);
-        
-        Ok(())
-    }
-
-    fn reset(&mut self) -> Result<(), DriverError> {
-        // Send HCI Reset command
-        let reset_cmd = [0x01, 0x03, 0x0C, 0x00];
-        self.send_command(&reset_cmd)?;
-        
-        // Wait for Command Complete event
-        let mut buffer = [0u8; 256];
-        let len = self.receive_data(&mut buffer)?;
-        
-        if len < 6 || buffer[0] != 0x04 || buffer[1] != 0x0E {
-            return Err(DriverError::InvalidResponse);
-        }
-        
-        Ok(())
-    }
-
-    fn get_address(&self) -> BluetoothAddress {
-        self.address
-    }
+fn known_name(vendor: u16, product: u16) -> Option<&'static str> {
+    BLUETOOTH_DEVICES.iter().find(|&&(v, p, _)| v == vendor && p == product).map(|&(_, _, name)| name)
+}
 
-    fn send_command(&mut self, data: &[u8]) -> Result<(), DriverError> {
-        // Commands go to control endpoint
-        self.send_usb_control(0x00, 0x00, 0x00, data)
-    }
+pub static USB_DRIVER: BtUsbDriver = BtUsbDriver;
 
-    fn send_acl_data(&mut self, data: &[u8]) -> Result<(), DriverError> {
-        // ACL data goes to bulk OUT endpoint
-        self.write_usb_bulk(EP_ACL_OUT, data)
-    }
+pub struct BtUsbDriver;
 
-    fn send_sco_data(&mut self, data: &[u8]) -> Result<(), DriverError> {
-        // SCO data goes to isochronous OUT endpoint
-        self.write_usb_isoc(EP_SCO_OUT, data)
+impl Driver for BtUsbDriver {
+    fn name(&self) -> &'static str {
+        "btusb"
     }
 
-    fn receive_data(&mut self, buffer: &mut [u8]) -> Result<usize, DriverError> {
-        // Try to read from interrupt endpoint first (HCI events)
-        if let Ok(len) = self.read_usb_interrupt(EP_EVENTS, buffer) {
-            if len > 0 {
-                return Ok(len);
-            }
-        }
-        
-        // Then try bulk endpoint (ACL data)
-        if let Ok(len) = self.read_usb_bulk(EP_ACL_IN, buffer) {
-            if len > 0 {
-                return Ok(len);
-            }
-        }
-        
-        // Finally try isochronous endpoint (SCO data)
-        self.read_usb_isoc(EP_SCO_IN, buffer)
+    fn bus(&self) -> BusType {
+        BusType::Usb
     }
 
-    fn load_firmware(&mut self) -> Result<(), DriverError> {
-        // Check if firmware loading is needed based on vendor/product ID
-        match (self.vendor_id, self.product_id) {
-            (0x0CF3, _) => {
-                // Atheros - load firmware
-                self.load_atheros_firmware()?;
-            },
-            (0x0A5C, _) | (0x0B05, _) => {
-                // Broadcom/ASUS - load firmware
-                self.load_broadcom_firmware()?;
-            },
-            (0x8087, _) => {
-                // Intel - load firmware
-                self.load_intel_firmware()?;
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbClass {
+                class: USB_CLASS_WIRELESS,
+                subclass: Some(SUBCLASS_RF),
+                protocol: Some(PROTOCOL_BLUETOOTH),
             },
-            (0x0BDA, _) => {
-                // Realtek - load firmware
-                self.load_realtek_firmware()?;
-            },
-            _ => {
-                // No firmware needed or generic device
-            }
-        }
-        
-        Ok(())
+            // Broadcom's dongles say vendor-class, with Bluetooth's
+            // subclass and protocol
+            Match::UsbId { vendor: 0x0A5C, product: 0x21E8 },
+            Match::UsbId { vendor: 0x0A5C, product: 0x21E6 },
+            Match::UsbId { vendor: 0x0A5C, product: 0x21EC },
+            Match::UsbId { vendor: 0x0A5C, product: 0x640B },
+        ]
     }
 
-    fn set_power(&mut self, on: bool) -> Result<(), DriverError> {
-        if on {
-            // Power on sequence
-            self.send_usb_control(0x40, 0x01, 0x01, &[])?;
-        } else {
-            // Power off sequence
-            self.send_usb_control(0x40, 0x01, 0x00, &[])?;
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, vendor, product, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        // The HCI interface, in its first setting: events in, and ACL both
+        // ways
+        let (events, acl_in, acl_out) = usb
+            .interfaces
+            .iter()
+            .filter(|interface| interface.alternate == 0)
+            .filter(|interface| {
+                let class = (interface.class, interface.subclass, interface.protocol);
+                class == (USB_CLASS_WIRELESS, SUBCLASS_RF, PROTOCOL_BLUETOOTH)
+                    || class == (USB_CLASS_VENDOR, SUBCLASS_RF, PROTOCOL_BLUETOOTH)
+            })
+            .find_map(|interface| Some((interface.interrupt_in()?, interface.bulk_in()?, interface.bulk_out()?)))
+            .ok_or(ProbeError::NoDevice)?;
+
+        let name = match (known_name(vendor, product), usb.product.as_str()) {
+            (Some(name), _) => String::from(name),
+            (None, "") => format!("USB Bluetooth {:04x}:{:04x}", vendor, product),
+            (None, product) => String::from(product),
+        };
+        let incoming = Arc::new(Mutex::new(Incoming::default()));
+        let transport = UsbTransport { address, acl_out: acl_out.address, incoming: incoming.clone() };
+        let adapter = crate::bluetooth::add_adapter(Box::new(transport), format!("USB device {}: {}", address, name));
+        DONGLES.lock().insert(address, Dongle { adapter, event_urb: None, acl_urb: None });
+
+        let event_urb =
+            submit(address, Stream::Events, events.address, events.max_packet_size as usize, incoming.clone());
+        let acl_urb = submit(address, Stream::Acl, acl_in.address, ACL_TRANSFER_SIZE, incoming);
+        let (event_urb, acl_urb) = match (event_urb, acl_urb) {
+            (Ok(event_urb), Ok(acl_urb)) => (event_urb, acl_urb),
+            (Err(e), _) | (_, Err(e)) => {
+                detach(address);
+                return Err(ProbeError::Failed(e));
+            }
+        };
+        if let Some(dongle) = DONGLES.lock().get_mut(&address) {
+            dongle.event_urb.get_or_insert(event_urb);
+            dongle.acl_urb.get_or_insert(acl_urb);
         }
         Ok(())
     }
-}
-
-impl UsbBluetoothAdapter {
-    fn load_atheros_firmware(&mut self) -> Result<(), DriverError> {
-        // Atheros AR3011/AR3012 firmware loading
-        log::info!("Loading Atheros Bluetooth firmware");
-        
-        // Send firmware download command
-        let cmd = [0x01, 0xFC, 0x1E, 0x00];
-        self.send_command(&cmd)?;
-        
-        // Load firmware chunks
-        // Firmware would be loaded from a file in a real implementation
-        
-        Ok(())
-    }
-
-    fn load_broadcom_firmware(&mut self) -> Result<(), DriverError> {
-        // Broadcom BCM20702/BCM43xx firmware loading
-        log::info!("Loading Broadcom Bluetooth firmware");
-        
-        // Reset device
-        let reset_cmd = [0x01, 0x03, 0x0C, 0x00];
-        self.send_command(&reset_cmd)?;
-        
-        // Download minidriver
-        let download_cmd = [0x01, 0x2E, 0xFC, 0x00];
-        self.send_command(&download_cmd)?;
-        
-        // Load firmware patches
-        // Firmware would be loaded from a file in a real implementation
-        
-        // Launch firmware
-        let launch_cmd = [0x01, 0x4E, 0xFC, 0x04, 0xFF, 0xFF, 0xFF, 0xFF];
-        self.send_command(&launch_cmd)?;
-        
-        Ok(())
-    }
-
-    fn load_intel_firmware(&mut self) -> Result<(), DriverError> {
-        // Intel firmware loading
-        log::info!("Loading Intel Bluetooth firmware");
-        
-        // Read version
-        let version_cmd = [0x01, 0x05, 0xFC, 0x00];
-        self.send_command(&version_cmd)?;
-        
-        // Enter manufacturer mode
-        let mfg_cmd = [0x01, 0x11, 0xFC, 0x02, 0x01, 0x00];
-        self.send_command(&mfg_cmd)?;
-        
-        // Load firmware
-        // Firmware would be loaded from a file in a real implementation
-        
-        // Exit manufacturer mode
-        let exit_cmd = [0x01, 0x11, 0xFC, 0x02, 0x00, 0x00];
-        self.send_command(&exit_cmd)?;
-        
-        // Reset device
-        let reset_cmd = [0x01, 0x03, 0x0C, 0x00];
-        self.send_command(&reset_cmd)?;
-        
-        Ok(())
-    }
-
-    fn load_realtek_firmware(&mut self) -> Result<(), DriverError> {
-        // Realtek RTL8723/RTL8761 firmware loading
-        log::info!("Loading Realtek Bluetooth firmware");
-        
-        // Read ROM version
-        let rom_cmd = [0x01, 0x6D, 0xFC, 0x00];
-        self.send_command(&rom_cmd)?;
-        
-        // Download firmware
-        // Firmware would be loaded from a file in a real implementation
-        
-        Ok(())
-    }
-}
 
-impl HciTransport for UsbBluetoothAdapter {
-    fn send(&mut self, data: &[u8]) -> Result<(), BluetoothError> {
-        if data.is_empty() {
-            return Err(BluetoothError::InvalidParameter);
-        }
-
-        match data[0] {
-            0x01 => {
-                // HCI Command packet
-                self.send_command(&data[1..]).map_err(|e| e.into())
-            },
-            0x02 => {
-                // ACL Data packet
-                self.send_acl_data(&data[1..]).map_err(|e| e.into())
-            },
-            0x03 => {
-                // SCO Data packet
-                self.send_sco_data(&data[1..]).map_err(|e| e.into())
-            },
-            _ => Err(BluetoothError::InvalidParameter),
+    fn remove(&self, device: &Device) {
+        if let Ident::Usb { address, .. } = device.ident {
+            detach(address);
         }
     }
-
-    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, BluetoothError> {
-        self.receive_data(buffer).map_err(|e| e.into())
-    }
-}
-
-pub fn scan() -> Option<BluetoothAddress> {
-    // Scan USB bus for Bluetooth adapters
-    // This would interface with the USB subsystem to enumerate devices
-    
-    // Look for devices with Bluetooth class
-    // Class: 0xE0 (Wireless Controller)
-    // Subclass: 0x01 (RF Controller)
-    // Protocol: 0x01 (Bluetooth)
-    
-    // For now, return None as we need USB subsystem integration
-    None
 }
 
-pub fn probe_device(vendor_id: u16, product_id: u16) -> Option<Box<dyn BluetoothDriver>> {
-    let mut adapter = UsbBluetoothAdapter::new(vendor_id, product_id);
-    
-    if adapter.init().is_ok() {
-        Some(Box::new(adapter))
-    } else {
-        None
+fn detach(address: u8) {
+    let Some(dongle) = DONGLES.lock().remove(&address) else {
+        return;
+    };
+    for id in [dongle.event_urb, dongle.acl_urb].into_iter().flatten() {
+        urb::cancel(id);
     }
+    crate::bluetooth::remove_adapter(dongle.adapter);
 }
 
 // Known USB Bluetooth device IDs
@@ -357,13 +261,11 @@ pub const BLUETOOTH_DEVICES: &[(u16, u16, &str)] = &[
     (0x8087, 0x0029, "Intel AX200 Bluetooth"),
     (0x8087, 0x0032, "Intel AX210 Bluetooth"),
     (0x8087, 0x0033, "Intel AX211 Bluetooth"),
-    
     // Broadcom
     (0x0A5C, 0x21E8, "Broadcom BCM20702A0"),
     (0x0A5C, 0x21E6, "Broadcom BCM20702A1"),
     (0x0A5C, 0x21EC, "Broadcom BCM20702A3"),
     (0x0A5C, 0x640B, "Broadcom BCM20703A1"),
-    
     // Realtek
     (0x0BDA, 0xB720, "Realtek RTL8723B"),
     (0x0BDA, 0xB721, "Realtek RTL8723BE"),
@@ -371,17 +273,14 @@ pub const BLUETOOTH_DEVICES: &[(u16, u16, &str)] = &[
     (0x0BDA, 0xC821, "Realtek RTL8821C"),
     (0x0BDA, 0xC822, "Realtek RTL8822B"),
     (0x0BDA, 0xC82F, "Realtek RTL8822CE"),
-    
     // Atheros/Qualcomm
     (0x0CF3, 0x3004, "Atheros AR3012"),
     (0x0CF3, 0x3005, "Atheros AR3011"),
     (0x0CF3, 0xE004, "Qualcomm Atheros QCA9565"),
     (0x0CF3, 0xE009, "Qualcomm Atheros QCA6174"),
-    
     // CSR/Cambridge Silicon Radio
     (0x0A12, 0x0001, "CSR BlueCore"),
-    
     // MediaTek
     (0x0E8D, 0x763F, "MediaTek MT7630E"),
     (0x0E8D, 0x7961, "MediaTek MT7921"),
-];
\ No newline at end of file
+];
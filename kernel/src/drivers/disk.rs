@@ -17,12 +17,18 @@ const ATA_SECONDARY_CTRL: u16 = 0x376;
 const ATA_CMD_READ_SECTORS: u8 = 0x20;
 const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
 const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xE0;
+const ATA_CMD_FLUSH_CACHE: u8 = 0xE7;
 
 // ATA status bits
 const ATA_STATUS_ERR: u8 = 0x01;
 const ATA_STATUS_DRQ: u8 = 0x08;
 const ATA_STATUS_BSY: u8 = 0x80;
 
+// A drive answers STANDBY IMMEDIATE once its spindle has stopped, which can
+// take seconds; about ten at 1 GHz. FLUSH CACHE is given as long.
+const STANDBY_TIMEOUT_CYCLES: u64 = 10_000_000_000;
+
 // Disk information
 #[derive(Debug, Clone)]
 pub struct DiskInfo {
@@ -38,6 +44,16 @@ pub trait DiskDriver: Send + Sync {
     fn read_sectors(&mut self, start_sector: u64, count: u32, buffer: &mut [u8]) -> Result<(), DiskError>;
     fn write_sectors(&mut self, start_sector: u64, count: u32, data: &[u8]) -> Result<(), DiskError>;
     fn get_info(&self) -> DiskInfo;
+
+    /// Spin the disk down; the next read or write brings it back
+    fn standby(&mut self) -> Result<(), DiskError> {
+        Err(DiskError::Unsupported)
+    }
+
+    /// Write out whatever the disk's own cache holds, before power is cut
+    fn flush_cache(&mut self) -> Result<(), DiskError> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -46,6 +62,7 @@ pub enum DiskError {
     IoError,
     InvalidSector,
     BufferTooSmall,
+    Unsupported,
 }
 
 // ATA/IDE disk driver
@@ -515,6 +532,28 @@ impl DiskDriver for AtaDisk {
     fn get_info(&self) -> DiskInfo {
         self.info.clone()
     }
+
+    fn standby(&mut self) -> Result<(), DiskError> {
+        unsafe {
+            self.drive_port.write(if self.is_master { 0xA0 } else { 0xB0 });
+            self.command_port.write(ATA_CMD_STANDBY_IMMEDIATE);
+        }
+        match self.wait_ready_with_timeout(STANDBY_TIMEOUT_CYCLES)? {
+            true => Ok(()),
+            false => Err(DiskError::IoError),
+        }
+    }
+
+    fn flush_cache(&mut self) -> Result<(), DiskError> {
+        unsafe {
+            self.drive_port.write(if self.is_master { 0xA0 } else { 0xB0 });
+            self.command_port.write(ATA_CMD_FLUSH_CACHE);
+        }
+        match self.wait_ready_with_timeout(STANDBY_TIMEOUT_CYCLES)? {
+            true => Ok(()),
+            false => Err(DiskError::IoError),
+        }
+    }
 }
 
 // Disk manager - manages all disk drivers
@@ -574,6 +613,12 @@ impl DiskManager {
     pub fn disk_count(&self) -> usize {
         self.disks.len()
     }
+    
+    /// Register a disk found after boot, returning its index
+    pub fn add_disk(&mut self, disk: Box<dyn DiskDriver>) -> usize {
+        self.disks.push(disk);
+        self.disks.len() - 1
+    }
 }
 
 lazy_static! {
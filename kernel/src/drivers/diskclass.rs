@@ -0,0 +1,129 @@
+//! The disk class driver
+//!
+//! A native driver in the I/O manager's model over the disks
+//! `disk::DISK_MANAGER` found: disk N becomes \Device\HarddiskN, linked from
+//! \DosDevices\PhysicalDriveN, so /dev/wdm/PhysicalDrive0 reads and writes
+//! disk 0 by IRPs and filter drivers can attach to it. Transfers are whole
+//! sectors at sector offsets, and go through the block I/O scheduler as the
+//! file systems' do.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use super::block;
+use super::disk::{DISK_MANAGER, SECTOR_SIZE};
+use super::io;
+use crate::nt::wdm::ntoskrnl::*;
+use crate::nt::wdm::types::*;
+
+const FILE_DEVICE_DISK: u32 = 0x07;
+const FILE_READ_ACCESS: u32 = 1;
+
+/// The disk's size in bytes, a u64
+pub const IOCTL_DISK_GET_LENGTH_INFO: u32 = ctl_code(FILE_DEVICE_DISK, 0x17, METHOD_BUFFERED, FILE_READ_ACCESS);
+
+#[repr(C)]
+struct Extension {
+    disk: usize,
+    sectors: u64,
+}
+
+unsafe fn extension(device: *mut DeviceObject) -> &'static Extension {
+    &*(*device).device_extension.cast::<Extension>()
+}
+
+fn link_name(disk: usize) -> OwnedUnicode {
+    OwnedUnicode::new(&format!("\\DosDevices\\PhysicalDrive{}", disk))
+}
+
+pub unsafe extern "win64" fn driver_entry(driver: *mut DriverObject, _registry_path: *mut UnicodeString) -> NTSTATUS {
+    let disks: Vec<u64> = {
+        let mut manager = DISK_MANAGER.lock();
+        let count = manager.disk_count();
+        (0..count).filter_map(|disk| manager.get_disk(disk).map(|found| found.get_info().sectors)).collect()
+    };
+    if disks.is_empty() {
+        return STATUS_NO_SUCH_DEVICE;
+    }
+    for (disk, sectors) in disks.into_iter().enumerate() {
+        let mut name = OwnedUnicode::new(&format!("\\Device\\Harddisk{}", disk));
+        let mut link = link_name(disk);
+        let mut device = ptr::null_mut();
+        let extension_size = size_of::<Extension>() as u32;
+        let status = IoCreateDevice(driver, extension_size, &mut name.string, FILE_DEVICE_DISK, 0, 0, &mut device);
+        if !nt_success(status) {
+            unload(driver);
+            return status;
+        }
+        (*device).device_extension.cast::<Extension>().write(Extension { disk, sectors });
+        (*device).flags |= DO_BUFFERED_IO;
+        (*device).alignment_requirement = SECTOR_SIZE as u32 - 1;
+        IoCreateSymbolicLink(&mut link.string, &mut name.string);
+    }
+
+    for major in [IRP_MJ_CREATE, IRP_MJ_CLEANUP, IRP_MJ_CLOSE] {
+        (*driver).major_function[major as usize] = Some(dispatch_open_close);
+    }
+    (*driver).major_function[IRP_MJ_READ as usize] = Some(dispatch_read_write);
+    (*driver).major_function[IRP_MJ_WRITE as usize] = Some(dispatch_read_write);
+    (*driver).major_function[IRP_MJ_DEVICE_CONTROL as usize] = Some(dispatch_device_control);
+    (*driver).driver_unload = Some(unload);
+    STATUS_SUCCESS
+}
+
+unsafe extern "win64" fn unload(driver: *mut DriverObject) {
+    while let Some(device) = (*driver).device_object.as_mut() {
+        let mut link = link_name(extension(device).disk);
+        IoDeleteSymbolicLink(&mut link.string);
+        IoDeleteDevice(device);
+    }
+}
+
+unsafe extern "win64" fn dispatch_open_close(_device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    io::complete(irp, STATUS_SUCCESS, 0)
+}
+
+unsafe extern "win64" fn dispatch_read_write(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    let stack = &*(*irp).current_stack_location;
+    // Parameters.Read/Write: Length, Key, ByteOffset
+    let (length, offset) = (stack.parameters[0] as usize, stack.parameters[2]);
+    let extension = extension(device);
+    if length % SECTOR_SIZE != 0 || offset % SECTOR_SIZE as u64 != 0 {
+        return io::complete(irp, STATUS_INVALID_PARAMETER, 0);
+    }
+    let sector = offset / SECTOR_SIZE as u64;
+    if sector >= extension.sectors {
+        return io::complete(irp, STATUS_END_OF_FILE, 0);
+    }
+    let count = ((length / SECTOR_SIZE) as u64).min(extension.sectors - sector) as u32;
+    if count == 0 {
+        return io::complete(irp, STATUS_SUCCESS, 0);
+    }
+    let bytes = count as usize * SECTOR_SIZE;
+    let buffer = core::slice::from_raw_parts_mut((*irp).system_buffer.cast::<u8>(), bytes);
+    let result = match stack.major_function {
+        IRP_MJ_READ => block::read(extension.disk, sector, count, buffer),
+        _ => block::write(extension.disk, sector, count, buffer),
+    };
+    match result {
+        Ok(()) => io::complete(irp, STATUS_SUCCESS, bytes as u64),
+        Err(_) => io::complete(irp, STATUS_IO_DEVICE_ERROR, 0),
+    }
+}
+
+unsafe extern "win64" fn dispatch_device_control(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    let stack = &*(*irp).current_stack_location;
+    // Parameters.DeviceIoControl: OutputBufferLength, IoControlCode
+    let (output_length, code) = (stack.parameters[0] as usize, stack.parameters[2] as u32);
+    if code != IOCTL_DISK_GET_LENGTH_INFO {
+        return io::complete(irp, STATUS_INVALID_DEVICE_REQUEST, 0);
+    }
+    if output_length < size_of::<u64>() {
+        return io::complete(irp, STATUS_BUFFER_TOO_SMALL, 0);
+    }
+    let bytes = extension(device).sectors * SECTOR_SIZE as u64;
+    (*irp).system_buffer.cast::<u64>().write_unaligned(bytes);
+    io::complete(irp, STATUS_SUCCESS, size_of::<u64>() as u64)
+}
@@ -0,0 +1,195 @@
+//! A disk encryption filter
+//!
+//! Attaches a device of its own over each \Device\HarddiskN and encrypts
+//! what is written through the stack with AES-XTS, a sector at a time with
+//! the sector number as the tweak. Reads are decrypted by a completion
+//! routine once the disk class driver below has filled the buffer. The key
+//! is set with IOCTL_DISKCRYPT_SET_KEY, sent to the disk as any other ioctl
+//! is; until then reads and writes fail as not ready, so nothing reaches
+//! the disk in the clear. Other requests pass down untouched.
+//!
+//! Only I/O through the device stack is encrypted: the file systems read
+//! and write the disks by the block layer, below it.
+
+use alloc::format;
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr;
+
+use super::disk::SECTOR_SIZE;
+use super::io;
+use crate::nt::wdm::ntoskrnl::*;
+use crate::nt::wdm::types::*;
+
+const FILE_DEVICE_DISK: u32 = 0x07;
+const FILE_WRITE_ACCESS: u32 = 2;
+
+/// Set the key: 32 bytes for AES-128-XTS or 64 for AES-256-XTS
+pub const IOCTL_DISKCRYPT_SET_KEY: u32 = ctl_code(FILE_DEVICE_DISK, 0x900, METHOD_BUFFERED, FILE_WRITE_ACCESS);
+
+#[repr(C)]
+struct Extension {
+    /// Where IRPs go on from here
+    lower: *mut DeviceObject,
+    lock: KSPIN_LOCK,
+    key: [u8; 64],
+    /// 0 until a key is set
+    key_length: usize,
+}
+
+unsafe fn extension(device: *mut DeviceObject) -> *mut Extension {
+    (*device).device_extension.cast()
+}
+
+// A copy of the key, taken under the lock
+unsafe fn key(device: *mut DeviceObject) -> Option<([u8; 64], usize)> {
+    let extension = extension(device);
+    let irql = KeAcquireSpinLockRaiseToDpc(&mut (*extension).lock);
+    let key = ((*extension).key, (*extension).key_length);
+    KeReleaseSpinLock(&mut (*extension).lock, irql);
+    (key.1 > 0).then_some(key)
+}
+
+// Encrypt or decrypt whole sectors in place, the first being `sector`
+fn crypt(key: &[u8], sector: u64, data: &mut [u8], encrypt: bool) -> bool {
+    for (index, chunk) in data.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+        let sector = sector + index as u64;
+        let result = match encrypt {
+            true => crate::crypto::aes_xts_encrypt(key, sector, chunk),
+            false => crate::crypto::aes_xts_decrypt(key, sector, chunk),
+        };
+        match result {
+            Ok(output) => chunk.copy_from_slice(&output),
+            Err(_) => return false,
+        }
+    }
+    true
+}
+
+pub unsafe extern "win64" fn driver_entry(driver: *mut DriverObject, _registry_path: *mut UnicodeString) -> NTSTATUS {
+    for disk in 0.. {
+        let mut target = OwnedUnicode::new(&format!("\\Device\\Harddisk{}", disk));
+        let mut device = ptr::null_mut();
+        let extension_size = size_of::<Extension>() as u32;
+        let status = IoCreateDevice(driver, extension_size, ptr::null_mut(), FILE_DEVICE_DISK, 0, 0, &mut device);
+        if !nt_success(status) {
+            unload(driver);
+            return status;
+        }
+        let mut lower = ptr::null_mut();
+        if !nt_success(IoAttachDevice(device, &mut target.string, &mut lower)) {
+            IoDeleteDevice(device);
+            break;
+        }
+        (*extension(device)).lower = lower;
+        KeInitializeSpinLock(&mut (*extension(device)).lock);
+        // Buffers are passed as the device below takes them
+        (*device).flags |= (*lower).flags & (DO_BUFFERED_IO | DO_DIRECT_IO);
+        (*device).device_type = (*lower).device_type;
+        (*device).characteristics = (*lower).characteristics;
+    }
+    if (*driver).device_object.is_null() {
+        return STATUS_NO_SUCH_DEVICE;
+    }
+
+    for major in 0..=IRP_MJ_MAXIMUM_FUNCTION {
+        (*driver).major_function[major] = Some(dispatch_pass);
+    }
+    (*driver).major_function[IRP_MJ_READ as usize] = Some(dispatch_read);
+    (*driver).major_function[IRP_MJ_WRITE as usize] = Some(dispatch_write);
+    (*driver).major_function[IRP_MJ_DEVICE_CONTROL as usize] = Some(dispatch_device_control);
+    (*driver).driver_unload = Some(unload);
+    STATUS_SUCCESS
+}
+
+unsafe extern "win64" fn unload(driver: *mut DriverObject) {
+    while let Some(device) = (*driver).device_object.as_mut() {
+        let extension = extension(device);
+        (*extension).key = [0; 64];
+        if !(*extension).lower.is_null() {
+            IoDetachDevice((*extension).lower);
+        }
+        IoDeleteDevice(device);
+    }
+}
+
+// Send the IRP on as it is
+unsafe fn pass_down(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    io::skip_current_stack_location(irp);
+    io::call_driver((*extension(device)).lower, irp)
+}
+
+unsafe extern "win64" fn dispatch_pass(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    pass_down(device, irp)
+}
+
+// Parameters.Read/Write: Length and ByteOffset, if both are whole sectors
+unsafe fn sectors(irp: *mut Irp) -> Option<(usize, u64)> {
+    let stack = &*(*irp).current_stack_location;
+    let (length, offset) = (stack.parameters[0] as usize, stack.parameters[2]);
+    let aligned = length % SECTOR_SIZE == 0 && offset % SECTOR_SIZE as u64 == 0;
+    (aligned && !(*irp).system_buffer.is_null()).then_some((length, offset / SECTOR_SIZE as u64))
+}
+
+unsafe extern "win64" fn dispatch_write(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    let Some((key, key_length)) = key(device) else {
+        return io::complete(irp, STATUS_DEVICE_NOT_READY, 0);
+    };
+    let Some((length, sector)) = sectors(irp) else {
+        return io::complete(irp, STATUS_INVALID_PARAMETER, 0);
+    };
+    // The system buffer is the I/O manager's copy, not the caller's
+    let data = core::slice::from_raw_parts_mut((*irp).system_buffer.cast::<u8>(), length);
+    if !crypt(&key[..key_length], sector, data, true) {
+        return io::complete(irp, STATUS_INVALID_PARAMETER, 0);
+    }
+    pass_down(device, irp)
+}
+
+unsafe extern "win64" fn dispatch_read(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    if key(device).is_none() {
+        return io::complete(irp, STATUS_DEVICE_NOT_READY, 0);
+    }
+    if sectors(irp).is_none() {
+        return io::complete(irp, STATUS_INVALID_PARAMETER, 0);
+    }
+    io::copy_current_stack_location_to_next(irp);
+    io::set_completion_routine(irp, read_complete, ptr::null_mut(), true, false, false);
+    io::call_driver((*extension(device)).lower, irp)
+}
+
+// Decrypt what the disk read, back in this driver's stack location
+unsafe extern "win64" fn read_complete(device: *mut DeviceObject, irp: *mut Irp, _context: *mut c_void) -> NTSTATUS {
+    if (*irp).pending_returned != 0 {
+        io::mark_pending(irp);
+    }
+    let (Some((key, key_length)), Some((length, sector))) = (key(device), sectors(irp)) else {
+        return STATUS_SUCCESS;
+    };
+    let read = ((*irp).io_status.information as usize).min(length) / SECTOR_SIZE * SECTOR_SIZE;
+    let data = core::slice::from_raw_parts_mut((*irp).system_buffer.cast::<u8>(), read);
+    if !crypt(&key[..key_length], sector, data, false) {
+        data.fill(0);
+        (*irp).io_status = IoStatusBlock { status: STATUS_IO_DEVICE_ERROR, information: 0 };
+    }
+    STATUS_SUCCESS
+}
+
+unsafe extern "win64" fn dispatch_device_control(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    let stack = &*(*irp).current_stack_location;
+    // Parameters.DeviceIoControl: InputBufferLength, IoControlCode
+    let (input_length, code) = (stack.parameters[1] as usize, stack.parameters[2] as u32);
+    if code != IOCTL_DISKCRYPT_SET_KEY {
+        return pass_down(device, irp);
+    }
+    if input_length != 32 && input_length != 64 {
+        return io::complete(irp, STATUS_INVALID_PARAMETER, 0);
+    }
+    let input = core::slice::from_raw_parts((*irp).system_buffer.cast::<u8>(), input_length);
+    let extension = extension(device);
+    let irql = KeAcquireSpinLockRaiseToDpc(&mut (*extension).lock);
+    (*extension).key[..input_length].copy_from_slice(input);
+    (*extension).key_length = input_length;
+    KeReleaseSpinLock(&mut (*extension).lock, irql);
+    io::complete(irp, STATUS_SUCCESS, 0)
+}
@@ -0,0 +1,305 @@
+//! The I/O manager: device stacks and IRPs
+//!
+//! Drivers built into the kernel and Windows drivers loaded through the WDM
+//! layer share one I/O model, Windows': a driver object with a dispatch
+//! routine per major function, device objects, and I/O request packets with
+//! a stack location for each device they pass through. The objects are the
+//! ones `nt::wdm::types` lays out as the DDK does, so a native driver in
+//! Rust and a .sys image can sit in the same stack and hand each other IRPs.
+//! A native driver's dispatch routines are `extern "win64"` for the same
+//! reason.
+//!
+//! A filter driver attaches a device of its own on top of another's stack.
+//! Requests for any device in a stack go to the top, and each driver either
+//! completes an IRP or passes it to the device below with `call_driver`,
+//! having set up the next stack location: copied for a completion routine,
+//! which runs on the way back up, or skipped when it has no more to do.
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::collections::BTreeMap;
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::nt::wdm::types::*;
+use crate::sync::Mutex;
+
+/// Drivers built into the kernel, loaded by these names as if from a file
+pub const BUILTIN: &[(&str, DriverInitialize)] = &[
+    ("disk.sys", super::diskclass::driver_entry),
+    ("diskcrypt.sys", super::diskcrypt::driver_entry),
+    ("null.sys", crate::nt::wdm::null::driver_entry),
+];
+
+// Each attached device by address, with the device it sits on; the
+// DEVOBJ_EXTENSION's AttachedTo on Windows
+static LOWER: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// An IRP with `stack_size` stack locations after it, none of them current
+pub fn allocate_irp(stack_size: i8) -> *mut Irp {
+    let stack_size = stack_size.max(1);
+    let size = size_of::<Irp>() + stack_size as usize * size_of::<IoStackLocation>();
+    let irp = unsafe { alloc_zeroed(Layout::from_size_align_unchecked(size, 16)) } as *mut Irp;
+    if irp.is_null() {
+        return irp;
+    }
+    unsafe {
+        (*irp).kind = IO_TYPE_IRP;
+        (*irp).size = size as u16;
+        (*irp).stack_count = stack_size;
+        (*irp).current_location = stack_size + 1;
+        // One past the last location, which the first IoCallDriver takes
+        (*irp).current_stack_location = irp.add(1).cast::<IoStackLocation>().add(stack_size as usize);
+    }
+    irp
+}
+
+pub unsafe fn free_irp(irp: *mut Irp) {
+    dealloc(irp.cast(), Layout::from_size_align_unchecked((*irp).size as usize, 16));
+}
+
+/// An MDL describing `length` bytes of kernel memory at `buffer`, which
+/// stays mapped for as long as the MDL is in use
+pub fn allocate_mdl(buffer: *mut u8, length: usize) -> *mut Mdl {
+    let start = buffer as u64 & !0xFFF;
+    let pages = ((buffer as u64 + length as u64 + 0xFFF - start) / 0x1000) as usize;
+    let size = size_of::<Mdl>() + pages * size_of::<u64>();
+    let mdl = unsafe { alloc_zeroed(Layout::from_size_align_unchecked(size, 8)) } as *mut Mdl;
+    let Some(header) = (unsafe { mdl.as_mut() }) else {
+        return mdl;
+    };
+    header.size = size as i16;
+    header.mdl_flags = MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL;
+    header.mapped_system_va = buffer.cast();
+    header.start_va = start as *mut c_void;
+    header.byte_count = length as u32;
+    header.byte_offset = (buffer as u64 & 0xFFF) as u32;
+    // The page frame numbers follow the header
+    let frames = unsafe { mdl.add(1).cast::<u64>() };
+    for page in 0..pages {
+        let frame = crate::memory::paging::translate_current(VirtAddr::new(start + page as u64 * 0x1000))
+            .map_or(0, |(physical, _)| physical.as_u64() >> 12);
+        unsafe { frames.add(page).write(frame) };
+    }
+    mdl
+}
+
+pub unsafe fn free_mdl(mdl: *mut Mdl) {
+    dealloc(mdl.cast(), Layout::from_size_align_unchecked((*mdl).size as usize, 8));
+}
+
+/// Send an IRP down to `device`, giving it the next stack location
+pub unsafe fn call_driver(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    (*irp).current_location -= 1;
+    if (*irp).current_location <= 0 {
+        panic!("io: NO_MORE_IRP_STACK_LOCATIONS sending IRP {:p} to {:p}", irp, device);
+    }
+    (*irp).current_stack_location = (*irp).current_stack_location.sub(1);
+    let stack = (*irp).current_stack_location;
+    (*stack).device_object = device;
+    let dispatch = (*(*device).driver_object).major_function[(*stack).major_function as usize];
+    dispatch.unwrap_or(invalid_device_request)(device, irp)
+}
+
+/// Complete an IRP: run the completion routines up the stack, then hand
+/// the result to whoever sent it, unless a routine keeps the IRP
+pub unsafe fn complete_request(irp: *mut Irp) {
+    let count = (*irp).stack_count;
+    while (*irp).current_location <= count {
+        let stack = (*irp).current_stack_location;
+        (*irp).pending_returned = (*stack).control & SL_PENDING_RETURNED;
+        (*irp).current_location += 1;
+        (*irp).current_stack_location = stack.add(1);
+        let invoke = match nt_success((*irp).io_status.status) {
+            _ if (*irp).cancel != 0 => SL_INVOKE_ON_CANCEL,
+            true => SL_INVOKE_ON_SUCCESS,
+            false => SL_INVOKE_ON_ERROR,
+        };
+        let above = ((*irp).current_location <= count).then(|| (*irp).current_stack_location);
+        match (*stack).completion_routine {
+            Some(routine) if (*stack).control & invoke != 0 => {
+                let device = above.map_or(ptr::null_mut(), |above| (*above).device_object);
+                if routine(device, irp, (*stack).context) == STATUS_MORE_PROCESSING_REQUIRED {
+                    return;
+                }
+            }
+            // IoMarkIrpPending on the driver above's behalf
+            _ => {
+                if let (Some(above), true) = (above, (*irp).pending_returned != 0) {
+                    (*above).control |= SL_PENDING_RETURNED;
+                }
+            }
+        }
+    }
+    if let Some(iosb) = (*irp).user_iosb.as_mut() {
+        *iosb = (*irp).io_status;
+    }
+    if let Some(done) = ((*irp).user_event as *const AtomicBool).as_ref() {
+        done.store(true, Ordering::Release);
+    }
+}
+
+/// Set an IRP's status and complete it; returns the status, for a
+/// dispatch routine to return in turn
+pub unsafe fn complete(irp: *mut Irp, status: NTSTATUS, information: u64) -> NTSTATUS {
+    (*irp).io_status = IoStatusBlock { status, information };
+    complete_request(irp);
+    status
+}
+
+/// What a driver object's unset major functions point at, as on Windows
+pub unsafe extern "win64" fn invalid_device_request(_device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    complete(irp, STATUS_INVALID_DEVICE_REQUEST, 0)
+}
+
+/// IoSkipCurrentIrpStackLocation: the device below gets this driver's
+/// stack location as it stands, and no completion routine runs for it
+pub unsafe fn skip_current_stack_location(irp: *mut Irp) {
+    (*irp).current_location += 1;
+    (*irp).current_stack_location = (*irp).current_stack_location.add(1);
+}
+
+/// IoCopyCurrentIrpStackLocationToNext, leaving out the completion routine
+pub unsafe fn copy_current_stack_location_to_next(irp: *mut Irp) {
+    let (current, next) = (&*(*irp).current_stack_location, &mut *(*irp).next_stack_location());
+    next.major_function = current.major_function;
+    next.minor_function = current.minor_function;
+    next.flags = current.flags;
+    next.control = 0;
+    next.parameters = current.parameters;
+    next.file_object = current.file_object;
+    next.completion_routine = None;
+    next.context = ptr::null_mut();
+}
+
+/// IoSetCompletionRoutine: run `routine` when the device below completes
+/// the IRP with a status in the given classes
+pub unsafe fn set_completion_routine(
+    irp: *mut Irp,
+    routine: IoCompletionRoutine,
+    context: *mut c_void,
+    on_success: bool,
+    on_error: bool,
+    on_cancel: bool,
+) {
+    let next = &mut *(*irp).next_stack_location();
+    next.completion_routine = Some(routine);
+    next.context = context;
+    next.control = 0;
+    let invoke = [(on_success, SL_INVOKE_ON_SUCCESS), (on_error, SL_INVOKE_ON_ERROR), (on_cancel, SL_INVOKE_ON_CANCEL)];
+    for (on, bit) in invoke {
+        if on {
+            next.control |= bit;
+        }
+    }
+}
+
+/// IoMarkIrpPending
+pub unsafe fn mark_pending(irp: *mut Irp) {
+    (*(*irp).current_stack_location).control |= SL_PENDING_RETURNED;
+}
+
+/// IoGetAttachedDevice: the top of `device`'s stack, where its IRPs go
+pub unsafe fn attached_top(mut device: *mut DeviceObject) -> *mut DeviceObject {
+    while !(*device).attached_device.is_null() {
+        device = (*device).attached_device;
+    }
+    device
+}
+
+/// The device `device` is attached to, if it is a filter or upper device
+pub fn lower_device(device: *mut DeviceObject) -> Option<*mut DeviceObject> {
+    LOWER.lock().get(&(device as usize)).map(|&lower| lower as *mut DeviceObject)
+}
+
+/// IoAttachDeviceToDeviceStack: put `source` on top of `target`'s stack;
+/// returns the device it now sits on, which is where it passes IRPs
+pub unsafe fn attach(source: *mut DeviceObject, target: *mut DeviceObject) -> Option<*mut DeviceObject> {
+    let mut lower = LOWER.lock();
+    if source == target || lower.contains_key(&(source as usize)) {
+        return None;
+    }
+    let top = attached_top(target);
+    (*top).attached_device = source;
+    (*source).stack_size = (*top).stack_size.saturating_add(1);
+    (*source).alignment_requirement = (*source).alignment_requirement.max((*top).alignment_requirement);
+    lower.insert(source as usize, top as usize);
+    Some(top)
+}
+
+/// IoDetachDevice: take whatever is attached to `target` off it
+pub unsafe fn detach(target: *mut DeviceObject) {
+    let upper = (*target).attached_device;
+    if upper.is_null() {
+        return;
+    }
+    (*target).attached_device = ptr::null_mut();
+    LOWER.lock().remove(&(upper as usize));
+}
+
+/// Take a device being deleted out of its stack: what was attached to it
+/// drops onto the device below, or off the stack if there is none
+pub unsafe fn remove_from_stack(device: *mut DeviceObject) {
+    let mut lower_map = LOWER.lock();
+    let lower = lower_map.remove(&(device as usize)).map(|lower| lower as *mut DeviceObject);
+    let upper = (*device).attached_device;
+    if let Some(lower) = lower {
+        (*lower).attached_device = upper;
+    }
+    if !upper.is_null() {
+        match lower {
+            Some(lower) => lower_map.insert(upper as usize, lower as usize),
+            None => lower_map.remove(&(upper as usize)),
+        };
+    }
+    (*device).attached_device = ptr::null_mut();
+}
+
+/// Send an IRP to the top of `device`'s stack, set up by `setup`, and wait
+/// for it to complete. An MDL the IRP carries is freed with it.
+pub unsafe fn send(
+    device: *mut DeviceObject,
+    file: *mut FileObject,
+    major: u8,
+    mode: i8,
+    setup: impl FnOnce(*mut Irp, &mut IoStackLocation),
+) -> IoStatusBlock {
+    let device = attached_top(device);
+    let irp = allocate_irp((*device).stack_size);
+    if irp.is_null() {
+        return IoStatusBlock { status: STATUS_INSUFFICIENT_RESOURCES, information: 0 };
+    }
+    let done = AtomicBool::new(false);
+    let mut iosb = IoStatusBlock { status: STATUS_PENDING, information: 0 };
+    (*irp).user_iosb = &mut iosb;
+    (*irp).user_event = &done as *const AtomicBool as *mut c_void;
+    (*irp).original_file_object = file;
+    (*irp).requestor_mode = mode;
+    let stack = &mut *(*irp).next_stack_location();
+    stack.major_function = major;
+    stack.file_object = file;
+    setup(irp, stack);
+
+    let status = call_driver(device, irp);
+    if status == STATUS_PENDING {
+        // Only an interrupt can complete it now
+        let enabled = interrupts::are_enabled();
+        while !done.load(Ordering::Acquire) {
+            interrupts::enable_and_hlt();
+        }
+        if !enabled {
+            interrupts::disable();
+        }
+    } else if !done.load(Ordering::Acquire) {
+        // Failed without being completed
+        iosb = IoStatusBlock { status, information: 0 };
+    }
+    if !(*irp).mdl_address.is_null() {
+        free_mdl((*irp).mdl_address);
+    }
+    free_irp(irp);
+    iosb
+}
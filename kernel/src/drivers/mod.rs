@@ -1,4 +1,8 @@
 // Device Drivers Subsystem for ReactOS
+//
+// The driver and device records here are the subsystem's bookkeeping. The
+// objects drivers work on, and the IRPs passed between them, are the I/O
+// manager's, in `io`.
 pub mod pci;
 pub mod usb;
 pub mod storage;
@@ -9,9 +13,12 @@ pub mod display;
 pub mod input;
 pub mod power;
 pub mod disk;
+pub mod block;
+pub mod diskclass;
+pub mod diskcrypt;
+pub mod io;
 pub mod mouse;
 pub mod bluetooth;
-pub mod wifi;
 
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -173,238 +180,12 @@ pub struct DriverObject {
     pub driver_type: DriverType,
     pub driver_start: Option<fn() -> NtStatus>,
     pub driver_unload: Option<fn()>,
-    pub major_functions: [Option<fn(&mut Irp) -> NtStatus>; 28],
-    pub fast_io_dispatch: Option<()>, // Temporarily simplified to avoid Send issues
     pub driver_init: Option<fn(&mut DriverObject) -> NtStatus>,
     pub driver_extension: Option<usize>, // Changed from raw pointer to usize for Send safety
     pub hardware_database: Option<String>,
     pub devices: Vec<Handle>,
 }
 
-// I/O Request Packet
-#[derive(Debug, Clone)]
-pub struct Irp {
-    pub mdl_address: Option<usize>, // Changed from raw pointer to usize for Send safety
-    pub flags: IrpFlags,
-    pub associated_irp: Option<Handle>,
-    pub thread_list_entry: ListEntry,
-    pub io_status: IoStatusBlock,
-    pub requestor_mode: ProcessorMode,
-    pub pending_returned: bool,
-    pub stack_count: u8,
-    pub current_location: u8,
-    pub cancel: bool,
-    pub cancel_irql: u8,
-    pub apc_environment: u8,
-    pub allocation_flags: u8,
-    pub user_iosb: Option<*mut IoStatusBlock>,
-    pub user_event: Option<Handle>,
-    pub overlay: IrpOverlay,
-    pub cancel_routine: Option<fn(&mut DeviceObject, &mut Irp)>,
-    pub user_buffer: Option<*mut u8>,
-    pub tail: IrpTail,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum ProcessorMode {
-    KernelMode = 0,
-    UserMode = 1,
-}
-
-bitflags::bitflags! {
-    #[derive(Debug, Clone)]
-    pub struct IrpFlags: u32 {
-        const IRP_NOCACHE = 0x00000001;
-        const IRP_PAGING_IO = 0x00000002;
-        const IRP_MOUNT_COMPLETION = 0x00000004;
-        const IRP_SYNCHRONOUS_API = 0x00000008;
-        const IRP_ASSOCIATED_IRP = 0x00000010;
-        const IRP_BUFFERED_IO = 0x00000020;
-        const IRP_DEALLOCATE_BUFFER = 0x00000040;
-        const IRP_INPUT_OPERATION = 0x00000080;
-        const IRP_SYNCHRONOUS_PAGING_IO = 0x00000100;
-        const IRP_CREATE_OPERATION = 0x00000200;
-        const IRP_READ_OPERATION = 0x00000400;
-        const IRP_WRITE_OPERATION = 0x00000800;
-        const IRP_CLOSE_OPERATION = 0x00001000;
-        const IRP_DEFER_IO_COMPLETION = 0x00002000;
-        const IRP_OB_QUERY_NAME = 0x00004000;
-        const IRP_HOLD_DEVICE_QUEUE = 0x00008000;
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct ListEntry {
-    pub flink: Option<*mut ListEntry>,
-    pub blink: Option<*mut ListEntry>,
-}
-
-#[derive(Debug, Clone)]
-pub struct IoStatusBlock {
-    pub status: NtStatus,
-    pub information: usize,
-}
-
-#[derive(Debug, Clone)]
-pub struct IrpOverlay {
-    pub async_parameters: AsyncParameters,
-}
-
-#[derive(Debug, Clone)]
-pub struct AsyncParameters {
-    pub user_apc_routine: Option<fn()>,
-    pub user_apc_context: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub struct IrpTail {
-    pub overlay: IrpTailOverlay,
-}
-
-#[derive(Debug, Clone)]
-pub struct IrpTailOverlay {
-    pub current_stack_location: Option<*mut IoStackLocation>,
-    pub packet_type: u32,
-    pub original_file_object: Option<Handle>,
-}
-
-#[derive(Debug, Clone)]
-pub struct IoStackLocation {
-    pub major_function: u8,
-    pub minor_function: u8,
-    pub flags: u8,
-    pub control: u8,
-    pub parameters: IoStackParameters,
-    pub device_object: Handle,
-    pub file_object: Option<Handle>,
-    pub completion_routine: Option<fn(&mut DeviceObject, &mut Irp, *mut u8) -> NtStatus>,
-    pub context: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub enum IoStackParameters {
-    Create(CreateParameters),
-    Read(ReadWriteParameters),
-    Write(ReadWriteParameters),
-    QueryInformation(QuerySetParameters),
-    SetInformation(QuerySetParameters),
-    QueryEa(QueryEaParameters),
-    SetEa(SetEaParameters),
-    FlushBuffers,
-    QueryVolumeInformation(QuerySetVolumeParameters),
-    SetVolumeInformation(QuerySetVolumeParameters),
-    DirectoryControl(DirectoryControlParameters),
-    FileSystemControl(FileSystemControlParameters),
-    DeviceIoControl(DeviceIoControlParameters),
-    InternalDeviceIoControl(DeviceIoControlParameters),
-    Shutdown,
-    LockControl(LockControlParameters),
-    CleanupClose,
-    CreateMailslot(CreateMailslotParameters),
-    QuerySecurity(QuerySetSecurityParameters),
-    SetSecurity(QuerySetSecurityParameters),
-    Power(PowerParameters),
-    SystemControl(SystemControlParameters),
-    DeviceChange(DeviceChangeParameters),
-    QueryQuota(QuerySetQuotaParameters),
-    SetQuota(QuerySetQuotaParameters),
-    Pnp(PnpParameters),
-}
-
-#[derive(Debug, Clone)]
-pub struct CreateParameters {
-    pub security_context: Option<*mut u8>,
-    pub options: u32,
-    pub file_attributes: u16,
-    pub share_access: u16,
-    pub ea_length: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct ReadWriteParameters {
-    pub length: u32,
-    pub key: u32,
-    pub byte_offset: u64,
-}
-
-#[derive(Debug, Clone)]
-pub struct QuerySetParameters {
-    pub length: u32,
-    pub file_information_class: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct QueryEaParameters {
-    pub length: u32,
-    pub ea_list: Option<*mut u8>,
-    pub ea_list_length: u32,
-    pub ea_index: Option<*mut u32>,
-    pub restart_scan: bool,
-}
-
-#[derive(Debug, Clone)]
-pub struct SetEaParameters {
-    pub length: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct QuerySetVolumeParameters {
-    pub length: u32,
-    pub fs_information_class: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct DirectoryControlParameters {
-    pub length: u32,
-    pub filename: Option<String>,
-    pub file_information_class: u32,
-    pub file_index: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct FileSystemControlParameters {
-    pub output_buffer_length: u32,
-    pub input_buffer_length: u32,
-    pub fs_control_code: u32,
-    pub type3_input_buffer: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub struct DeviceIoControlParameters {
-    pub output_buffer_length: u32,
-    pub input_buffer_length: u32,
-    pub io_control_code: u32,
-    pub type3_input_buffer: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub struct LockControlParameters {
-    pub length: u64,
-    pub key: u32,
-    pub byte_offset: u64,
-}
-
-#[derive(Debug, Clone)]
-pub struct CreateMailslotParameters {
-    pub max_message_size: u32,
-    pub mailslot_quota: u32,
-    pub read_timeout: u64,
-}
-
-#[derive(Debug, Clone)]
-pub struct QuerySetSecurityParameters {
-    pub security_information: u32,
-    pub length: u32,
-}
-
-#[derive(Debug, Clone)]
-pub struct PowerParameters {
-    pub system_context: u32,
-    pub type_: PowerStateType,
-    pub state: PowerState,
-    pub shutdown_type: PowerAction,
-}
-
 #[derive(Debug, Clone, Copy)]
 pub enum PowerStateType {
     SystemPowerState = 0,
@@ -435,97 +216,6 @@ pub enum PowerAction {
     PowerActionWarmEject = 7,
 }
 
-#[derive(Debug, Clone)]
-pub struct SystemControlParameters {
-    pub provider_id: Option<*mut u8>,
-    pub data_path: Option<*mut u8>,
-    pub buffer_length: u32,
-    pub buffer: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub struct DeviceChangeParameters {
-    pub size: u16,
-    pub system_buffer: Option<*mut u8>,
-}
-
-#[derive(Debug, Clone)]
-pub struct QuerySetQuotaParameters {
-    pub length: u32,
-    pub sid_list: Option<*mut u8>,
-    pub sid_list_length: u32,
-    pub start_sid: Option<*mut u8>,
-    pub restart_scan: bool,
-}
-
-#[derive(Debug, Clone)]
-pub struct PnpParameters {
-    pub minor_function: u8,
-    pub data: PnpData,
-}
-
-#[derive(Debug, Clone)]
-pub enum PnpData {
-    StartDevice {
-        allocated_resources: Option<*mut u8>,
-        allocated_resources_translated: Option<*mut u8>,
-    },
-    QueryRemoveDevice,
-    RemoveDevice,
-    CancelRemoveDevice,
-    StopDevice,
-    QueryStopDevice,
-    CancelStopDevice,
-    QueryDeviceRelations {
-        type_: DeviceRelationType,
-    },
-    QueryInterface {
-        interface_type: String,
-        size: u16,
-        version: u16,
-        interface: Option<*mut u8>,
-        interface_specific_data: Option<*mut u8>,
-    },
-    QueryCapabilities {
-        capabilities: Option<*mut DeviceCapabilities>,
-    },
-    QueryResources,
-    QueryResourceRequirements,
-    QueryDeviceText {
-        device_text_type: DeviceTextType,
-        locale_id: u32,
-    },
-    FilterResourceRequirements,
-    ReadConfig {
-        which_space: u32,
-        buffer: Option<*mut u8>,
-        offset: u32,
-        length: u32,
-    },
-    WriteConfig {
-        which_space: u32,
-        buffer: Option<*mut u8>,
-        offset: u32,
-        length: u32,
-    },
-    Eject,
-    SetLock {
-        lock: bool,
-    },
-    QueryId {
-        id_type: BusQueryIdType,
-    },
-    QueryPnpDeviceState,
-    QueryBusInformation,
-    DeviceUsageNotification {
-        in_path: bool,
-        reserved: [bool; 3],
-        type_: DeviceUsageNotificationType,
-    },
-    SurpriseRemoval,
-    QueryLegacyBusInformation,
-}
-
 #[derive(Debug, Clone, Copy)]
 pub enum DeviceRelationType {
     BusRelations,
@@ -586,38 +276,6 @@ pub enum DeviceUsageNotificationType {
     DeviceUsageTypeBoot,
 }
 
-#[derive(Debug, Clone)]
-pub struct FastIoDispatch {
-    pub size_of_fast_io_dispatch: u32,
-    pub fast_io_check_if_possible: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_read: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_write: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_query_basic_info: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_query_standard_info: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_lock: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_unlock_single: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_unlock_all: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_unlock_all_by_key: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_device_control: Option<fn(&mut Irp) -> bool>,
-    pub acquire_file_for_nt_create_section: Option<fn()>,
-    pub release_file_for_nt_create_section: Option<fn()>,
-    pub fast_io_detach_device: Option<fn()>,
-    pub fast_io_query_network_open_info: Option<fn(&mut Irp) -> bool>,
-    pub acquire_for_mod_write: Option<fn() -> NtStatus>,
-    pub mdl_read: Option<fn(&mut Irp) -> bool>,
-    pub mdl_read_complete: Option<fn(&mut Irp) -> bool>,
-    pub prepare_mdl_write: Option<fn(&mut Irp) -> bool>,
-    pub mdl_write_complete: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_read_compressed: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_write_compressed: Option<fn(&mut Irp) -> bool>,
-    pub mdl_read_complete_compressed: Option<fn(&mut Irp) -> bool>,
-    pub mdl_write_complete_compressed: Option<fn(&mut Irp) -> bool>,
-    pub fast_io_query_open: Option<fn(&mut Irp) -> bool>,
-    pub release_for_mod_write: Option<fn() -> NtStatus>,
-    pub acquire_for_cc_flush: Option<fn() -> NtStatus>,
-    pub release_for_cc_flush: Option<fn() -> NtStatus>,
-}
-
 // Driver Manager
 pub struct DriverManager {
     drivers: BTreeMap<Handle, DriverObject>,
@@ -863,14 +521,6 @@ impl DriverManager {
         crate::println!("Loading driver: {} ({:?})", driver_name, driver_type);
 
         let handle = self.allocate_handle();
-        let mut major_functions = [None; 28];
-
-        // Set up default major functions (using function pointers)
-        major_functions[0] = Some(default_create_close as fn(&mut Irp) -> NtStatus); // IRP_MJ_CREATE
-        major_functions[1] = Some(default_create_close as fn(&mut Irp) -> NtStatus); // IRP_MJ_CLOSE
-        major_functions[2] = Some(default_read_write as fn(&mut Irp) -> NtStatus);   // IRP_MJ_READ
-        major_functions[3] = Some(default_read_write as fn(&mut Irp) -> NtStatus);   // IRP_MJ_WRITE
-        major_functions[14] = Some(default_device_control as fn(&mut Irp) -> NtStatus); // IRP_MJ_DEVICE_CONTROL
 
         let driver = DriverObject {
             handle,
@@ -878,8 +528,6 @@ impl DriverManager {
             driver_type,
             driver_start: None,
             driver_unload: None,
-            major_functions,
-            fast_io_dispatch: None,
             driver_init: None,
             driver_extension: None,
             hardware_database: None,
@@ -951,32 +599,6 @@ impl DriverManager {
         Ok(device_handle)
     }
 
-    pub fn attach_device(&mut self, source_device: Handle, target_device: Handle) -> Result<Handle, NtStatus> {
-        if let Some(source) = self.devices.get_mut(&source_device) {
-            source.attached_device = Some(target_device);
-            crate::println!("Device {:?} attached to {:?}", source_device, target_device);
-            Ok(target_device)
-        } else {
-            Err(NtStatus::InvalidDeviceRequest)
-        }
-    }
-
-    pub fn send_irp(&mut self, device_handle: Handle, irp: &mut Irp) -> NtStatus {
-        if let Some(device) = self.devices.get(&device_handle) {
-            let driver_handle = device.driver;
-            if let Some(driver) = self.drivers.get(&driver_handle) {
-                if let Some(stack_location) = irp.tail.overlay.current_stack_location {
-                    let major_function = unsafe { (*stack_location).major_function };
-                    
-                    if let Some(dispatch_routine) = driver.major_functions[major_function as usize] {
-                        return dispatch_routine(irp);
-                    }
-                }
-            }
-        }
-        NtStatus::InvalidDeviceRequest
-    }
-
     pub fn initialize_pnp(&mut self) -> NtStatus {
         crate::println!("Initializing Plug and Play manager");
         self.pnp_manager.initialize();
@@ -1336,34 +958,6 @@ impl Default for BatteryPolicy {
     }
 }
 
-// Default IRP handlers
-fn default_create_close(irp: &mut Irp) -> NtStatus {
-    irp.io_status.status = NtStatus::Success;
-    irp.io_status.information = 0;
-    complete_irp(irp, NtStatus::Success);
-    NtStatus::Success
-}
-
-fn default_read_write(irp: &mut Irp) -> NtStatus {
-    irp.io_status.status = NtStatus::InvalidDeviceRequest;
-    irp.io_status.information = 0;
-    complete_irp(irp, NtStatus::InvalidDeviceRequest);
-    NtStatus::InvalidDeviceRequest
-}
-
-fn default_device_control(irp: &mut Irp) -> NtStatus {
-    irp.io_status.status = NtStatus::InvalidDeviceRequest;
-    irp.io_status.information = 0;
-    complete_irp(irp, NtStatus::InvalidDeviceRequest);
-    NtStatus::InvalidDeviceRequest
-}
-
-fn complete_irp(irp: &mut Irp, status: NtStatus) {
-    irp.io_status.status = status;
-    // Would normally call IoCompleteRequest here
-    crate::println!("IRP completed with status: {:?}", status);
-}
-
 // Driver loading and management functions
 pub fn io_create_driver(driver_name: &str, driver_init: fn(&mut DriverObject) -> NtStatus) -> Result<Handle, NtStatus> {
     let mut manager = DRIVER_MANAGER.lock();
@@ -1403,16 +997,6 @@ pub fn io_delete_device(device_handle: Handle) -> NtStatus {
     manager.delete_device(device_handle)
 }
 
-pub fn io_attach_device_to_device_stack(source_device: Handle, target_device: Handle) -> Result<Handle, NtStatus> {
-    let mut manager = DRIVER_MANAGER.lock();
-    manager.attach_device(source_device, target_device)
-}
-
-pub fn io_call_driver(device_handle: Handle, irp: &mut Irp) -> NtStatus {
-    let mut manager = DRIVER_MANAGER.lock();
-    manager.send_irp(device_handle, irp)
-}
-
 // Public API functions
 pub fn initialize_driver_subsystem() -> NtStatus {
     crate::println!("Initializing Driver Subsystem");
@@ -44,6 +44,9 @@ impl MouseState {
 
 #[derive(Debug, Clone, Copy)]
 pub struct MousePacket {
+    // Where the pointer is after the motion
+    pub x: i16,
+    pub y: i16,
     pub dx: i16,
     pub dy: i16,
     pub left_button: bool,
@@ -225,17 +228,6 @@ impl MouseDriver {
         
         dy = -dy;
         
-        self.current_state.x = (self.current_state.x + dx)
-            .max(0)
-            .min(self.screen_width as i16 - 1);
-        
-        self.current_state.y = (self.current_state.y + dy)
-            .max(0)
-            .min(self.screen_height as i16 - 1);
-        
-        self.current_state.left_button = (flags & 0x01) != 0;
-        self.current_state.right_button = (flags & 0x02) != 0;
-        self.current_state.middle_button = (flags & 0x04) != 0;
         self.current_state.x_overflow = (flags & 0x40) != 0;
         self.current_state.y_overflow = (flags & 0x80) != 0;
         
@@ -245,7 +237,34 @@ impl MouseDriver {
             0
         };
         
+        self.apply(dx, dy, flags & 0x07, z_delta);
+    }
+    
+    /// Motion and buttons from another pointing device, such as a touchpad:
+    /// `dy` is positive downwards, and the buttons are left, right and
+    /// middle from bit 0
+    pub fn inject(&mut self, dx: i16, dy: i16, buttons: u8, z_delta: i8) {
+        self.current_state.x_overflow = false;
+        self.current_state.y_overflow = false;
+        self.apply(dx, dy, buttons, z_delta);
+    }
+    
+    fn apply(&mut self, dx: i16, dy: i16, buttons: u8, z_delta: i8) {
+        self.current_state.x = self.current_state.x.saturating_add(dx)
+            .max(0)
+            .min(self.screen_width as i16 - 1);
+        
+        self.current_state.y = self.current_state.y.saturating_add(dy)
+            .max(0)
+            .min(self.screen_height as i16 - 1);
+        
+        self.current_state.left_button = (buttons & 0x01) != 0;
+        self.current_state.right_button = (buttons & 0x02) != 0;
+        self.current_state.middle_button = (buttons & 0x04) != 0;
+        
         let packet = MousePacket {
+            x: self.current_state.x,
+            y: self.current_state.y,
             dx,
             dy,
             left_button: self.current_state.left_button,
@@ -298,4 +317,11 @@ pub fn init() -> Result<(), &'static str> {
 pub fn handle_mouse_interrupt() {
     let mut driver = MOUSE_DRIVER.lock();
     driver.handle_interrupt();
-}
\ No newline at end of file
+}
+
+pub fn inject(dx: i16, dy: i16, buttons: u8, z_delta: i8) {
+    // The PS/2 interrupt takes the same lock
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        MOUSE_DRIVER.lock().inject(dx, dy, buttons, z_delta);
+    });
+}
@@ -178,6 +178,10 @@ pub struct PrintJob {
     pub until_time: u64,
     pub size_bytes: u64,
     pub position: u32,
+    /// What has been written so far, handed over at EndDocPrinter
+    pub data: Vec<u8>,
+    /// The job's id in the kernel print spooler once submitted there
+    pub spooler_job: Option<u32>,
 }
 
 impl PrintJob {
@@ -200,6 +204,8 @@ impl PrintJob {
             until_time: 0,
             size_bytes: 0,
             position: 0,
+            data: Vec::new(),
+            spooler_job: None,
         }
     }
 }
@@ -362,6 +368,13 @@ impl PrintSpooler {
         fax_printer.capabilities.languages = vec![String::from("TIFF")];
         self.printers.insert(fax_printer.name.clone(), fax_printer);
 
+        // Printers the kernel spooler drives, which take real output
+        if let Some(subsystem) = crate::printing::get_subsystem().read().as_ref() {
+            for printer in subsystem.list_printers() {
+                self.add_spooler_printer(&printer.name, &printer.uri);
+            }
+        }
+
         // Set PDF as default
         self.default_printer = Some(String::from("Microsoft Print to PDF"));
 
@@ -389,6 +402,15 @@ impl PrintSpooler {
         NtStatus::Success
     }
 
+    /// List a printer of the kernel spooler, whose jobs go out through it
+    pub fn add_spooler_printer(&mut self, name: &str, uri: &str) {
+        let mut printer = PrinterInfo::new(String::from(name), PrinterType::Network);
+        printer.driver_name = String::from("Kernel Print Spooler");
+        printer.port_name = String::from(uri);
+        printer.comment = String::from(uri);
+        self.printers.insert(printer.name.clone(), printer);
+    }
+
     pub fn remove_printer(&mut self, printer_name: &str) -> NtStatus {
         if self.printers.remove(printer_name).is_some() {
             // Remove default if this was it
@@ -469,11 +491,11 @@ impl PrintSpooler {
         if let Some(job) = self.print_jobs.get_mut(&job_id) {
             job.size_bytes += data.len() as u64;
             job.status = PrintJobStatus::Spooling;
+            job.data.extend_from_slice(data);
             
             crate::println!("Print: Writing {} bytes to job {} (total: {} bytes)",
                            data.len(), job_id, job.size_bytes);
             
-            // Simulate writing to spool file
             Ok(data.len())
         } else {
             Err(NtStatus::InvalidHandle)
@@ -490,7 +512,34 @@ impl PrintSpooler {
             crate::println!("Print: Finished document for job {} ({} bytes total)",
                            job_id, job.size_bytes);
             
-            // Start printing simulation
+            if let Some(subsystem) = crate::printing::get_subsystem().read().as_ref() {
+                if let Some(printer) = subsystem.find_printer(&job.printer_name) {
+                    let user = crate::security::accounts::token_user_name(crate::process::current_token())
+                        .unwrap_or_else(|| job.user_name.clone());
+                    let data = core::mem::take(&mut job.data);
+                    return match subsystem.submit_job(
+                        printer.id,
+                        user,
+                        job.document_name.clone(),
+                        data,
+                        crate::printing::PrintOptions::default(),
+                        crate::printing::job::JobPriority::Normal,
+                    ) {
+                        Ok(spooler_job) => {
+                            job.spooler_job = Some(spooler_job);
+                            job.status = PrintJobStatus::Printing;
+                            NtStatus::Success
+                        }
+                        Err(e) => {
+                            crate::println!("Print: Spooler refused job {}: {}", job_id, e);
+                            job.status = PrintJobStatus::Error;
+                            NtStatus::DeviceNotReady
+                        }
+                    };
+                }
+            }
+            
+            // No real device behind this printer, so printing is simulated
             self.start_printing_job(job_id);
             
             NtStatus::Success
@@ -525,6 +574,12 @@ impl PrintSpooler {
 
     pub fn cancel_job(&mut self, job_id: u32) -> NtStatus {
         if let Some(job) = self.print_jobs.remove(&job_id) {
+            if let (Some(spooler_job), Some(subsystem)) =
+                (job.spooler_job, crate::printing::get_subsystem().read().as_ref())
+            {
+                let _ = subsystem.cancel_job(spooler_job);
+            }
+
             // Update printer job count
             if let Some(printer) = self.printers.get_mut(&job.printer_name) {
                 if printer.jobs_count > 0 {
@@ -542,6 +597,37 @@ impl PrintSpooler {
         }
     }
 
+    /// Pick up how jobs handed to the kernel spooler have got on
+    pub fn refresh_jobs(&mut self) {
+        let subsystem = crate::printing::get_subsystem().read();
+        let Some(subsystem) = subsystem.as_ref() else {
+            return;
+        };
+        for job in self.print_jobs.values_mut() {
+            let Some(spooler_job) = job.spooler_job.filter(|_| job.status == PrintJobStatus::Printing) else {
+                continue;
+            };
+            let Some(state) = subsystem.get_job(spooler_job) else {
+                continue;
+            };
+            job.pages_printed = state.pages_printed;
+            job.total_pages = state.total_pages.max(state.pages_printed);
+            job.status = match state.status {
+                crate::printing::job::JobStatus::Completed => PrintJobStatus::Complete,
+                crate::printing::job::JobStatus::Failed => PrintJobStatus::Error,
+                crate::printing::job::JobStatus::Cancelled => PrintJobStatus::Deleting,
+                crate::printing::job::JobStatus::Paused => PrintJobStatus::Paused,
+                _ => continue,
+            };
+            if let Some(printer) = self.printers.get_mut(&job.printer_name) {
+                printer.jobs_count = printer.jobs_count.saturating_sub(1);
+                if printer.jobs_count == 0 {
+                    printer.status = PrinterStatus::Ready;
+                }
+            }
+        }
+    }
+
     pub fn get_job_info(&self, job_id: u32) -> Option<&PrintJob> {
         self.print_jobs.get(&job_id)
     }
@@ -748,7 +834,31 @@ pub fn print_cancel_job(job_id: u32) -> NtStatus {
 
 pub fn print_enum_jobs(printer_name: Option<&str>) -> Vec<u32> {
     unsafe {
-        PRINT_SPOOLER.as_ref()
-            .map_or(Vec::new(), |spooler| spooler.enum_jobs(printer_name))
+        PRINT_SPOOLER.as_mut().map_or(Vec::new(), |spooler| {
+            spooler.refresh_jobs();
+            spooler.enum_jobs(printer_name)
+        })
+    }
+}
+
+/// Make a printer added to the kernel spooler visible to Win32 programs
+pub fn print_add_printer(name: &str, uri: &str) -> NtStatus {
+    unsafe {
+        if let Some(ref mut spooler) = PRINT_SPOOLER {
+            spooler.add_spooler_printer(name, uri);
+            NtStatus::Success
+        } else {
+            NtStatus::DeviceNotReady
+        }
+    }
+}
+
+pub fn print_remove_printer(name: &str) -> NtStatus {
+    unsafe {
+        if let Some(ref mut spooler) = PRINT_SPOOLER {
+            spooler.remove_printer(name)
+        } else {
+            NtStatus::DeviceNotReady
+        }
     }
-}
\ No newline at end of file
+}
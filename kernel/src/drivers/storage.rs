@@ -1318,24 +1318,49 @@ pub fn get_storage_device_info(index: usize) -> Option<String> {
     }
 }
 
-pub fn read_storage_device(device_number: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, NtStatus> {
+pub fn get_storage_partitions(device_number: u32) -> Vec<PartitionInformation> {
     unsafe {
+        STORAGE_SUBSYSTEM.as_ref()
+            .and_then(|storage| storage.class_driver.get_partition_info(device_number))
+            .map_or(Vec::new(), |partitions| partitions.to_vec())
+    }
+}
+
+crate::declare_trace_event!(pub TRACE_BLOCK_RQ_ISSUE, "block", "block_rq_issue", ["device", "offset", "bytes", "write"]);
+crate::declare_trace_event!(pub TRACE_BLOCK_RQ_COMPLETE, "block", "block_rq_complete", ["device", "offset", "bytes", "write", "status"]);
+
+fn trace_block_complete(device_number: u32, offset: u64, write: bool, result: &Result<usize, NtStatus>) {
+    let (bytes, status) = match result {
+        Ok(bytes) => (*bytes as u64, NtStatus::Success as u64),
+        Err(status) => (0, *status as u64),
+    };
+    TRACE_BLOCK_RQ_COMPLETE.emit(&[device_number as u64, offset, bytes, write as u64, status]);
+}
+
+pub fn read_storage_device(device_number: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, NtStatus> {
+    TRACE_BLOCK_RQ_ISSUE.emit(&[device_number as u64, offset, buffer.len() as u64, 0]);
+    let result = unsafe {
         if let Some(ref mut storage) = STORAGE_SUBSYSTEM {
             storage.read_device(device_number, offset, buffer)
         } else {
             Err(NtStatus::DeviceNotReady)
         }
-    }
+    };
+    trace_block_complete(device_number, offset, false, &result);
+    result
 }
 
 pub fn write_storage_device(device_number: u32, offset: u64, buffer: &[u8]) -> Result<usize, NtStatus> {
-    unsafe {
+    TRACE_BLOCK_RQ_ISSUE.emit(&[device_number as u64, offset, buffer.len() as u64, 1]);
+    let result = unsafe {
         if let Some(ref mut storage) = STORAGE_SUBSYSTEM {
             storage.write_device(device_number, offset, buffer)
         } else {
             Err(NtStatus::DeviceNotReady)
         }
-    }
+    };
+    trace_block_complete(device_number, offset, true, &result);
+    result
 }
 
 pub fn flush_storage_device(device_number: u32) -> NtStatus {
@@ -0,0 +1,1061 @@
+//! FAT32 checks
+//!
+//! The FAT is read whole. Every cluster chain is followed from its
+//! directory entry, the root directory's from the boot sector, and each
+//! cluster is claimed by the first chain to reach it:
+//! - a link to a free, bad or out of range cluster ends the chain before
+//!   it, as does a link to a cluster already claimed, a cross-link or a
+//!   loop
+//! - a file's size must fit in its chain, and its chain end with the
+//!   cluster holding its last byte
+//!
+//! Clusters the FAT has in use that no chain reaches are lost. Repair
+//! saves each lost chain in the root directory as FILEnnnn.CHK, or frees
+//! it when the root directory is full. Short names are checked, and the
+//! "." and ".." entries of each directory. Last, the copies of the FAT
+//! are compared and the FSInfo free count corrected.
+
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::Report;
+use crate::drivers::block;
+use crate::drivers::disk::SECTOR_SIZE;
+
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_END_MIN: u32 = 0x0FFF_FFF8;
+const FAT_END: u32 = 0x0FFF_FFFF;
+const ENTRIES_PER_SECTOR: usize = SECTOR_SIZE / 4;
+// In FAT[1]; cleared while mounted, and after an I/O error
+const CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+const NO_HARD_ERROR: u32 = 0x0400_0000;
+// Extended flags: only the FAT numbered in the low bits is in use
+const NO_MIRRORING: u16 = 0x0080;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+// Stands for a first byte of 0xE5
+const ENTRY_KANJI_E5: u8 = 0x05;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// Deeper directories are not followed
+const MAX_DEPTH: usize = 64;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn set_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Whether `boot` is a FAT32 boot sector
+pub(super) fn is_fat32(boot: &[u8]) -> bool {
+    boot[510] == 0x55
+        && boot[511] == 0xAA
+        && u16_at(boot, 11) as usize == SECTOR_SIZE
+        && u16_at(boot, 17) == 0
+        && u16_at(boot, 22) == 0
+        && u32_at(boot, 36) != 0
+}
+
+struct Volume {
+    disk: usize,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    fats: u32,
+    // The FAT in use, and whether the others mirror it
+    active_fat: u32,
+    mirrored: bool,
+    data_start: u32,
+    // Data clusters, numbered from 2
+    clusters: u32,
+    root_cluster: u32,
+    fs_info: u16,
+    fat: Vec<u32>,
+    // FAT sectors changed since it was read
+    changed: BTreeSet<usize>,
+}
+
+impl Volume {
+    fn open(disk: usize, boot: &[u8]) -> Result<Self, &'static str> {
+        let sectors_per_cluster = boot[13] as u32;
+        let fat_start = u16_at(boot, 14) as u32;
+        let fats = boot[16] as u32;
+        let total_sectors = u32_at(boot, 32);
+        let fat_sectors = u32_at(boot, 36);
+        let flags = u16_at(boot, 40);
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err("invalid sectors per cluster");
+        }
+        if fat_start == 0 || fats == 0 {
+            return Err("invalid reserved sectors or FAT count");
+        }
+        let data_start = fats.checked_mul(fat_sectors).and_then(|size| size.checked_add(fat_start));
+        let data_start = data_start.filter(|&start| start < total_sectors).ok_or("FATs run past the volume")?;
+        let clusters = (total_sectors - data_start) / sectors_per_cluster;
+        if (clusters as usize + 2).div_ceil(ENTRIES_PER_SECTOR) > fat_sectors as usize {
+            return Err("FAT is too small for the volume");
+        }
+        let mirrored = flags & NO_MIRRORING == 0;
+        let active_fat = if mirrored { 0 } else { (flags & 0x0F) as u32 };
+        if active_fat >= fats {
+            return Err("active FAT does not exist");
+        }
+        let volume = Self {
+            disk,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            fats,
+            active_fat,
+            mirrored,
+            data_start,
+            clusters,
+            root_cluster: u32_at(boot, 44),
+            fs_info: u16_at(boot, 48),
+            fat: Vec::new(),
+            changed: BTreeSet::new(),
+        };
+        if !volume.valid(volume.root_cluster) {
+            return Err("invalid root directory cluster");
+        }
+        Ok(volume)
+    }
+
+    fn fat_sector(&self, copy: u32, sector: usize) -> u64 {
+        (self.fat_start + copy * self.fat_sectors) as u64 + sector as u64
+    }
+
+    fn read_fat(&self, copy: u32, sector: usize, count: usize) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; count * SECTOR_SIZE];
+        block::read(self.disk, self.fat_sector(copy, sector), count as u32, &mut data)
+            .map_err(|_| "cannot read the FAT")?;
+        Ok(data)
+    }
+
+    fn load_fat(&mut self) -> Result<(), &'static str> {
+        // Only the part of the FAT that maps clusters
+        let sectors = (self.clusters as usize + 2).div_ceil(ENTRIES_PER_SECTOR);
+        let mut fat = Vec::with_capacity(sectors * ENTRIES_PER_SECTOR);
+        for first in (0..sectors).step_by(64) {
+            let data = self.read_fat(self.active_fat, first, (sectors - first).min(64))?;
+            fat.extend(data.chunks_exact(4).map(|entry| u32_at(entry, 0)));
+        }
+        self.fat = fat;
+        Ok(())
+    }
+
+    fn fat_bytes(&self, sector: usize) -> Vec<u8> {
+        let entries = &self.fat[sector * ENTRIES_PER_SECTOR..(sector + 1) * ENTRIES_PER_SECTOR];
+        entries.iter().flat_map(|entry| entry.to_le_bytes()).collect()
+    }
+
+    // Write the changed FAT sectors to every copy in use
+    fn write_fat(&mut self) -> Result<(), &'static str> {
+        for &sector in &self.changed {
+            let data = self.fat_bytes(sector);
+            for copy in 0..self.fats {
+                if self.mirrored || copy == self.active_fat {
+                    block::write(self.disk, self.fat_sector(copy, sector), 1, &data)
+                        .map_err(|_| "cannot write the FAT")?;
+                }
+            }
+        }
+        self.changed.clear();
+        Ok(())
+    }
+
+    fn valid(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters + 2
+    }
+
+    fn next(&self, cluster: u32) -> u32 {
+        self.fat[cluster as usize] & FAT_MASK
+    }
+
+    // The top four bits of an entry are kept
+    fn set(&mut self, cluster: u32, value: u32) {
+        let entry = &mut self.fat[cluster as usize];
+        *entry = (*entry & !FAT_MASK) | value;
+        self.changed.insert(cluster as usize / ENTRIES_PER_SECTOR);
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start as u64 + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+
+    fn read_clusters(&self, clusters: &[u32]) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; clusters.len() * self.cluster_bytes()];
+        for (&cluster, buffer) in clusters.iter().zip(data.chunks_exact_mut(self.cluster_bytes())) {
+            block::read(self.disk, self.cluster_sector(cluster), self.sectors_per_cluster, buffer)
+                .map_err(|_| "cannot read a directory")?;
+        }
+        Ok(data)
+    }
+
+    fn write_cluster(&self, cluster: u32, data: &[u8]) -> Result<(), &'static str> {
+        block::write(self.disk, self.cluster_sector(cluster), self.sectors_per_cluster, data)
+            .map_err(|_| "cannot write a directory")
+    }
+}
+
+/// Whether FAT[1] says the volume on `disk` was not cleanly unmounted
+pub(super) fn is_dirty(disk: usize, boot: &[u8]) -> Result<bool, &'static str> {
+    let volume = Volume::open(disk, boot)?;
+    let sector = volume.read_fat(volume.active_fat, 0, 1)?;
+    let flags = u32_at(&sector, 4);
+    Ok(flags & CLEAN_SHUTDOWN == 0 || flags & NO_HARD_ERROR == 0)
+}
+
+fn short_name(name: &[u8]) -> String {
+    let mut text = String::new();
+    for (index, &byte) in name.iter().enumerate() {
+        if index == 8 && name[8] != b' ' {
+            text.push('.');
+        }
+        let byte = if index == 0 && byte == ENTRY_KANJI_E5 { ENTRY_DELETED } else { byte };
+        if byte != b' ' {
+            text.push(if byte.is_ascii_graphic() { byte as char } else { '?' });
+        }
+    }
+    text
+}
+
+fn valid_short_name(name: &[u8]) -> bool {
+    const INVALID: &[u8] = b"\"*+,./:;<=>?[\\]|";
+    name[0] != b' '
+        && name.iter().enumerate().all(|(index, &byte)| {
+            (byte >= 0x20 || (index == 0 && byte == ENTRY_KANJI_E5))
+                && !INVALID.contains(&byte)
+                && !byte.is_ascii_lowercase()
+        })
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path == "\\" {
+        format!("\\{}", name)
+    } else {
+        format!("{}\\{}", path, name)
+    }
+}
+
+struct Checker {
+    volume: Volume,
+    repair: bool,
+    report: Report,
+    claimed: Vec<bool>,
+    root_clusters: Vec<u32>,
+}
+
+// A directory whose entries are still to be checked
+struct Pending {
+    cluster: u32,
+    parent: u32,
+    path: String,
+    depth: usize,
+}
+
+impl Checker {
+    // Follow the chain from `first`, which is valid and unclaimed, and claim
+    // its clusters. A bad link or a link to a claimed cluster ends it, and
+    // with repair becomes its end.
+    fn chain(&mut self, first: u32, path: &str) -> Vec<u32> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        loop {
+            self.claimed[cluster as usize] = true;
+            clusters.push(cluster);
+            let next = self.volume.next(cluster);
+            if next >= FAT_END_MIN {
+                return clusters;
+            }
+            let problem = if !self.in_use(next) {
+                format!("{}: cluster {} links to {:#x}, which is not a cluster in use", path, cluster, next)
+            } else if self.claimed[next as usize] {
+                format!("{}: cluster {} links to cluster {}, which is already in use", path, cluster, next)
+            } else {
+                cluster = next;
+                continue;
+            };
+            self.report.problem(self.repair, problem);
+            if self.repair {
+                self.volume.set(cluster, FAT_END);
+            }
+            return clusters;
+        }
+    }
+
+    // Whether a cluster is one the FAT has in use, neither free nor bad
+    fn in_use(&self, cluster: u32) -> bool {
+        self.volume.valid(cluster) && !matches!(self.volume.next(cluster), FAT_FREE | FAT_BAD)
+    }
+
+    // Whether `first` can start a chain, reporting it if not
+    fn check_first(&mut self, first: u32, path: &str) -> bool {
+        if first == 0 || (self.in_use(first) && !self.claimed[first as usize]) {
+            return true;
+        }
+        let why = if !self.volume.valid(first) {
+            "is not a cluster"
+        } else if self.claimed[first as usize] {
+            "is already in use"
+        } else {
+            "is not in use"
+        };
+        self.report.problem(self.repair, format!("{}: first cluster {} {}", path, first, why));
+        false
+    }
+
+    // Check a file's chain against its size, trimming whichever is longer.
+    // Returns the entry's new size and first cluster, if they change.
+    fn file(&mut self, first: u32, size: u32, path: &str) -> Option<(u32, u32)> {
+        let clusters = if first == 0 { Vec::new() } else { self.chain(first, path) };
+        let cluster_bytes = self.volume.cluster_bytes() as u64;
+        let capacity = clusters.len() as u64 * cluster_bytes;
+        let needed = (size as u64).div_ceil(cluster_bytes) as usize;
+        if size as u64 > capacity {
+            let text = format!("{}: size is {} bytes but only {} are allocated", path, size, capacity);
+            self.report.problem(self.repair, text);
+            return Some((capacity as u32, first));
+        }
+        if clusters.len() > needed {
+            let text = format!("{}: {} clusters are allocated past the end of the file", path, clusters.len() - needed);
+            self.report.problem(self.repair, text);
+            if self.repair {
+                if needed > 0 {
+                    self.volume.set(clusters[needed - 1], FAT_END);
+                }
+                for &cluster in &clusters[needed..] {
+                    self.volume.set(cluster, FAT_FREE);
+                    self.claimed[cluster as usize] = false;
+                }
+            }
+            return Some((size, if needed == 0 { 0 } else { first }));
+        }
+        None
+    }
+
+    fn directory(&mut self, pending: Pending, work: &mut Vec<Pending>) -> Result<(), &'static str> {
+        let Pending { cluster, parent, path, depth } = pending;
+        let is_root = cluster == self.volume.root_cluster;
+        let clusters = self.chain(cluster, &path);
+        let mut data = self.volume.read_clusters(&clusters)?;
+        let cluster_bytes = self.volume.cluster_bytes();
+        let mut changed = BTreeSet::new();
+
+        for offset in (0..data.len()).step_by(DIR_ENTRY_SIZE) {
+            let entry = &mut data[offset..offset + DIR_ENTRY_SIZE];
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => continue,
+                _ => {}
+            }
+            let attributes = entry[11];
+            if attributes & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME || attributes & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let name = short_name(&entry[..11]);
+            let first = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+            let size = u32_at(entry, 28);
+
+            if name == "." || name == ".." {
+                let expected = match (name.as_str(), parent == self.volume.root_cluster) {
+                    (".", _) => cluster,
+                    (_, true) => 0,
+                    _ => parent,
+                };
+                if first != expected {
+                    let text = format!("{}: \"{}\" names cluster {}, not {}", path, name, first, expected);
+                    self.report.problem(self.repair, text);
+                    set_u16(entry, 20, (expected >> 16) as u16);
+                    set_u16(entry, 26, expected as u16);
+                    changed.insert(offset / cluster_bytes);
+                }
+                continue;
+            }
+
+            let entry_path = join(&path, &name);
+            if !valid_short_name(&entry[..11]) {
+                self.report.problem(false, format!("{}: invalid short name", entry_path));
+            }
+            if !self.check_first(first, &entry_path) {
+                // Nothing is left to point at
+                set_u16(entry, 20, 0);
+                set_u16(entry, 26, 0);
+                set_u32(entry, 28, 0);
+                if attributes & ATTR_DIRECTORY != 0 {
+                    entry[0] = ENTRY_DELETED;
+                }
+                changed.insert(offset / cluster_bytes);
+                continue;
+            }
+
+            if attributes & ATTR_DIRECTORY != 0 {
+                self.report.directories += 1;
+                if size != 0 {
+                    self.report.problem(self.repair, format!("{}: directory has a size of {} bytes", entry_path, size));
+                    set_u32(entry, 28, 0);
+                    changed.insert(offset / cluster_bytes);
+                }
+                if first == 0 {
+                    self.report.problem(self.repair, format!("{}: directory has no clusters", entry_path));
+                    entry[0] = ENTRY_DELETED;
+                    changed.insert(offset / cluster_bytes);
+                } else if depth + 1 >= MAX_DEPTH {
+                    self.report.problem(false, format!("{}: directories nest too deep to check", entry_path));
+                } else {
+                    // Claimed now, so a second entry naming it is a cross-link
+                    self.claimed[first as usize] = true;
+                    work.push(Pending { cluster: first, parent: cluster, path: entry_path, depth: depth + 1 });
+                }
+                continue;
+            }
+
+            self.report.files += 1;
+            if let Some((size, first)) = self.file(first, size, &entry_path) {
+                let entry = &mut data[offset..offset + DIR_ENTRY_SIZE];
+                set_u16(entry, 20, (first >> 16) as u16);
+                set_u16(entry, 26, first as u16);
+                set_u32(entry, 28, size);
+                changed.insert(offset / cluster_bytes);
+            }
+        }
+
+        if self.repair {
+            for index in changed {
+                let bytes = &data[index * cluster_bytes..(index + 1) * cluster_bytes];
+                self.volume.write_cluster(clusters[index], bytes)?;
+            }
+        }
+        if is_root {
+            self.root_clusters = clusters;
+        }
+        Ok(())
+    }
+
+    // Walk every directory from the root, last found first
+    fn directories(&mut self) -> Result<(), &'static str> {
+        let root = Pending { cluster: self.volume.root_cluster, parent: 0, path: String::from("\\"), depth: 0 };
+        let mut work = vec![root];
+        while let Some(pending) = work.pop() {
+            self.directory(pending, &mut work)?;
+        }
+        Ok(())
+    }
+
+    // Save a lost chain in the root directory as FILEnnnn.CHK, the first
+    // name free from `number` on; false if the root directory is full
+    fn save_lost(&mut self, first: u32, clusters: usize, number: &mut u32) -> Result<bool, &'static str> {
+        let mut data = self.volume.read_clusters(&self.root_clusters)?;
+        let names: BTreeSet<Vec<u8>> = data.chunks_exact(DIR_ENTRY_SIZE).map(|entry| entry[..11].to_vec()).collect();
+        let slot = (0..data.len()).step_by(DIR_ENTRY_SIZE).find(|&o| matches!(data[o], ENTRY_END | ENTRY_DELETED));
+        let Some(offset) = slot else { return Ok(false) };
+        let name = loop {
+            if *number >= 10_000 {
+                return Ok(false);
+            }
+            let name = format!("FILE{:04}CHK", number);
+            *number += 1;
+            if !names.contains(name.as_bytes()) {
+                break name;
+            }
+        };
+
+        let cluster_bytes = self.volume.cluster_bytes();
+        let entry = &mut data[offset..offset + DIR_ENTRY_SIZE];
+        entry.fill(0);
+        entry[..11].copy_from_slice(name.as_bytes());
+        entry[11] = ATTR_ARCHIVE;
+        set_u16(entry, 20, (first >> 16) as u16);
+        set_u16(entry, 26, first as u16);
+        set_u32(entry, 28, (clusters * cluster_bytes).min(u32::MAX as usize) as u32);
+        let index = offset / cluster_bytes;
+        self.volume
+            .write_cluster(self.root_clusters[index], &data[index * cluster_bytes..(index + 1) * cluster_bytes])?;
+        Ok(true)
+    }
+
+    // Clusters in use that no chain reached, gathered into the chains they
+    // form; those no lost cluster links to start one
+    fn lost_clusters(&mut self) -> Result<(), &'static str> {
+        let end = self.volume.clusters + 2;
+        let lost: Vec<u32> = (2..end)
+            .filter(|&c| !self.claimed[c as usize] && !matches!(self.volume.next(c), FAT_FREE | FAT_BAD))
+            .collect();
+        if lost.is_empty() {
+            return Ok(());
+        }
+        let mut linked = vec![false; end as usize];
+        for &cluster in &lost {
+            let next = self.volume.next(cluster);
+            if self.volume.valid(next) {
+                linked[next as usize] = true;
+            }
+        }
+        let mut chains = Vec::new();
+        for &head in lost.iter().filter(|&&c| !linked[c as usize]) {
+            let clusters = self.chain(head, &format!("lost chain at cluster {}", head));
+            chains.push((head, clusters));
+        }
+        let text = format!("{} lost clusters in {} chains", lost.len(), chains.len());
+        self.report.problem(self.repair, text);
+        if !self.repair {
+            return Ok(());
+        }
+
+        let mut number = 0;
+        for (head, clusters) in chains {
+            if !self.save_lost(head, clusters.len(), &mut number)? {
+                for cluster in clusters {
+                    self.volume.set(cluster, FAT_FREE);
+                }
+            }
+        }
+        // What is left is in loops with no way in
+        for cluster in lost {
+            if !self.claimed[cluster as usize] {
+                self.volume.set(cluster, FAT_FREE);
+            }
+        }
+        Ok(())
+    }
+
+    // Compare the other copies of the FAT with the one in use. With repair
+    // every FAT sector is written, to every copy.
+    fn compare_fats(&mut self) -> Result<(), &'static str> {
+        if !self.volume.mirrored {
+            return Ok(());
+        }
+        let sectors = self.volume.fat.len() / ENTRIES_PER_SECTOR;
+        for copy in 1..self.volume.fats {
+            let mut differs = false;
+            for first in (0..sectors).step_by(64) {
+                let data = self.volume.read_fat(copy, first, (sectors - first).min(64))?;
+                let ours = (first..sectors.min(first + 64)).flat_map(|sector| self.volume.fat_bytes(sector));
+                if !data.iter().copied().eq(ours) {
+                    differs = true;
+                    break;
+                }
+            }
+            if differs {
+                self.report.problem(self.repair, format!("FAT copy {} differs from the first", copy + 1));
+                self.volume.changed.extend(0..sectors);
+            }
+        }
+        Ok(())
+    }
+
+    fn fs_info(&mut self, free: u32) -> Result<(), &'static str> {
+        if self.volume.fs_info == 0 || self.volume.fs_info == 0xFFFF {
+            return Ok(());
+        }
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        let lba = self.volume.fs_info as u64;
+        block::read(self.volume.disk, lba, 1, &mut sector).map_err(|_| "cannot read FSInfo")?;
+        if u32_at(&sector, 0) != FSINFO_LEAD_SIGNATURE || u32_at(&sector, 484) != FSINFO_STRUCT_SIGNATURE {
+            self.report.problem(false, String::from("FSInfo sector is not valid"));
+            return Ok(());
+        }
+        let count = u32_at(&sector, FSINFO_FREE_COUNT);
+        if count != FSINFO_UNKNOWN && count != free {
+            self.report.problem(self.repair, format!("FSInfo says {} clusters are free, not {}", count, free));
+            if self.repair {
+                set_u32(&mut sector, FSINFO_FREE_COUNT, free);
+                block::write(self.volume.disk, lba, 1, &sector).map_err(|_| "cannot write FSInfo")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn check(disk: usize, boot: &[u8], repair: bool) -> Result<Report, &'static str> {
+    let mut volume = Volume::open(disk, boot)?;
+    volume.load_fat()?;
+    let mut report = Report::new("FAT32");
+    report.dirty = volume.fat[1] & CLEAN_SHUTDOWN == 0 || volume.fat[1] & NO_HARD_ERROR == 0;
+    report.cluster_size = volume.cluster_bytes() as u64;
+    report.clusters = volume.clusters as u64;
+    let claimed = vec![false; volume.clusters as usize + 2];
+    let mut checker = Checker { volume, repair, report, claimed, root_clusters: Vec::new() };
+
+    // Before any repair changes the FAT in use
+    checker.compare_fats()?;
+    checker.directories()?;
+    checker.lost_clusters()?;
+
+    let end = checker.volume.clusters + 2;
+    let free = (2..end).filter(|&c| checker.volume.next(c) == FAT_FREE).count() as u32;
+    checker.report.free_clusters = free as u64;
+    checker.report.bad_clusters = (2..end).filter(|&c| checker.volume.next(c) == FAT_BAD).count() as u64;
+    checker.fs_info(free)?;
+
+    if repair {
+        if checker.report.unfixed() == 0 {
+            checker.volume.fat[1] |= CLEAN_SHUTDOWN | NO_HARD_ERROR;
+            checker.volume.changed.insert(0);
+        }
+        checker.volume.write_fat()?;
+    }
+    Ok(checker.report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
+    use crate::drivers::disk::{DiskDriver, DiskError, DiskInfo, DISK_MANAGER};
+    use spin::Mutex;
+
+    // A volume of 100 one-sector clusters: FSInfo in sector 1, two
+    // one-sector FATs from sector 4, and the root directory in cluster 2
+    const RESERVED: usize = 4;
+    const FS_INFO: usize = 1;
+    const TOTAL_SECTORS: usize = 106;
+    const DATA_START: usize = RESERVED + 2;
+    const ROOT: u32 = 2;
+
+    struct MemoryDisk(Arc<Mutex<Vec<u8>>>);
+
+    impl DiskDriver for MemoryDisk {
+        fn read_sectors(&mut self, start_sector: u64, count: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+            let start = start_sector as usize * SECTOR_SIZE;
+            let data = self.0.lock();
+            let source = data.get(start..start + count as usize * SECTOR_SIZE).ok_or(DiskError::InvalidSector)?;
+            buffer[..source.len()].copy_from_slice(source);
+            Ok(())
+        }
+
+        fn write_sectors(&mut self, start_sector: u64, count: u32, data: &[u8]) -> Result<(), DiskError> {
+            let start = start_sector as usize * SECTOR_SIZE;
+            let length = count as usize * SECTOR_SIZE;
+            let mut disk = self.0.lock();
+            disk.get_mut(start..start + length).ok_or(DiskError::InvalidSector)?.copy_from_slice(&data[..length]);
+            Ok(())
+        }
+
+        fn get_info(&self) -> DiskInfo {
+            DiskInfo {
+                name: "mem".to_string(),
+                sectors: (self.0.lock().len() / SECTOR_SIZE) as u64,
+                sector_size: SECTOR_SIZE,
+                model: "memory".to_string(),
+                serial: String::new(),
+            }
+        }
+    }
+
+    struct Image(Vec<u8>);
+
+    impl Image {
+        fn new() -> Self {
+            let mut data = vec![0u8; TOTAL_SECTORS * SECTOR_SIZE];
+            let boot = &mut data[..SECTOR_SIZE];
+            boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+            boot[3..11].copy_from_slice(b"MSWIN4.1");
+            set_u16(boot, 11, SECTOR_SIZE as u16);
+            boot[13] = 1;
+            set_u16(boot, 14, RESERVED as u16);
+            boot[16] = 2;
+            boot[21] = 0xF8;
+            set_u32(boot, 32, TOTAL_SECTORS as u32);
+            set_u32(boot, 36, 1);
+            set_u32(boot, 44, ROOT);
+            set_u16(boot, 48, FS_INFO as u16);
+            boot[510..].copy_from_slice(&[0x55, 0xAA]);
+
+            let fs_info = &mut data[FS_INFO * SECTOR_SIZE..(FS_INFO + 1) * SECTOR_SIZE];
+            set_u32(fs_info, 0, FSINFO_LEAD_SIGNATURE);
+            set_u32(fs_info, 484, FSINFO_STRUCT_SIGNATURE);
+            set_u32(fs_info, FSINFO_FREE_COUNT, FSINFO_UNKNOWN);
+            fs_info[510..].copy_from_slice(&[0x55, 0xAA]);
+
+            let mut image = Image(data);
+            image.set_fat(0, 0x0FFF_FFF8);
+            image.set_fat(1, FAT_END);
+            image.set_fat(ROOT, FAT_END);
+            image
+        }
+
+        fn fat_entry(copy: usize, cluster: u32) -> usize {
+            (RESERVED + copy) * SECTOR_SIZE + cluster as usize * 4
+        }
+
+        fn fat(&self, copy: usize, cluster: u32) -> u32 {
+            u32_at(&self.0, Self::fat_entry(copy, cluster))
+        }
+
+        // In both copies
+        fn set_fat(&mut self, cluster: u32, value: u32) {
+            for copy in 0..2 {
+                set_u32(&mut self.0, Self::fat_entry(copy, cluster), value);
+            }
+        }
+
+        fn chain(&mut self, clusters: &[u32]) {
+            for pair in clusters.windows(2) {
+                self.set_fat(pair[0], pair[1]);
+            }
+            self.set_fat(*clusters.last().unwrap(), FAT_END);
+        }
+
+        fn cluster(&self, cluster: u32) -> &[u8] {
+            let start = (DATA_START + cluster as usize - 2) * SECTOR_SIZE;
+            &self.0[start..start + SECTOR_SIZE]
+        }
+
+        // Add an entry to a one-cluster directory, giving its offset
+        fn entry(&mut self, directory: u32, name: &[u8; 11], attributes: u8, first: u32, size: u32) -> usize {
+            let start = (DATA_START + directory as usize - 2) * SECTOR_SIZE;
+            let offset = (start..start + SECTOR_SIZE).step_by(DIR_ENTRY_SIZE).find(|&o| self.0[o] == ENTRY_END).unwrap();
+            let entry = &mut self.0[offset..offset + DIR_ENTRY_SIZE];
+            entry[..11].copy_from_slice(name);
+            entry[11] = attributes;
+            set_u16(entry, 20, (first >> 16) as u16);
+            set_u16(entry, 26, first as u16);
+            set_u32(entry, 28, size);
+            offset
+        }
+
+        fn file(&mut self, directory: u32, name: &[u8; 11], clusters: &[u32], size: u32) -> usize {
+            if !clusters.is_empty() {
+                self.chain(clusters);
+            }
+            self.entry(directory, name, ATTR_ARCHIVE, clusters.first().copied().unwrap_or(0), size)
+        }
+
+        fn directory(&mut self, parent: u32, name: &[u8; 11], cluster: u32) -> usize {
+            self.chain(&[cluster]);
+            self.entry(cluster, b".          ", ATTR_DIRECTORY, cluster, 0);
+            self.entry(cluster, b"..         ", ATTR_DIRECTORY, if parent == ROOT { 0 } else { parent }, 0);
+            self.entry(parent, name, ATTR_DIRECTORY, cluster, 0)
+        }
+
+        fn set_free_count(&mut self, count: u32) {
+            set_u32(&mut self.0, FS_INFO * SECTOR_SIZE + FSINFO_FREE_COUNT, count);
+        }
+
+        fn first_cluster(&self, offset: usize) -> u32 {
+            (u16_at(&self.0, offset + 20) as u32) << 16 | u16_at(&self.0, offset + 26) as u32
+        }
+
+        fn size(&self, offset: usize) -> u32 {
+            u32_at(&self.0, offset + 28)
+        }
+
+        // A root with README.TXT, and DOCS holding NOTES.TXT
+        fn sample() -> Self {
+            let mut image = Image::new();
+            image.file(ROOT, b"README  TXT", &[3], 100);
+            image.directory(ROOT, b"DOCS       ", 4);
+            image.file(4, b"NOTES   TXT", &[5, 6], 600);
+            image.set_free_count(95);
+            image
+        }
+
+        fn disk(&self) -> (usize, Arc<Mutex<Vec<u8>>>) {
+            let data = Arc::new(Mutex::new(self.0.clone()));
+            let disk = DISK_MANAGER.lock().add_disk(Box::new(MemoryDisk(data.clone())));
+            (disk, data)
+        }
+
+        // Check the image, giving the report and the image as the check left it
+        fn check(&self, repair: bool) -> (Report, Image) {
+            let (disk, data) = self.disk();
+            let report = check(disk, &self.0[..SECTOR_SIZE], repair).unwrap();
+            let after = Image(data.lock().clone());
+            (report, after)
+        }
+    }
+
+    fn texts(report: &Report) -> Vec<&str> {
+        report.problems.iter().map(|problem| problem.text.as_str()).collect()
+    }
+
+    fn has(report: &Report, text: &str) -> bool {
+        report.problems.iter().any(|problem| problem.text.contains(text))
+    }
+
+    #[test]
+    fn test_clean_volume() {
+        let image = Image::sample();
+        assert!(is_fat32(&image.0[..SECTOR_SIZE]));
+        let (report, after) = image.check(true);
+        assert!(report.problems.is_empty());
+        assert_eq!((report.files, report.directories), (2, 1));
+        assert_eq!((report.clusters, report.free_clusters, report.bad_clusters), (100, 95, 0));
+        assert_eq!(report.cluster_size, SECTOR_SIZE as u64);
+        assert!(!report.dirty);
+        assert!(after.0 == image.0);
+    }
+
+    #[test]
+    fn test_dirty_flag() {
+        let mut image = Image::sample();
+        image.set_fat(1, FAT_END & !CLEAN_SHUTDOWN);
+        let (disk, _) = image.disk();
+        assert!(is_dirty(disk, &image.0[..SECTOR_SIZE]).unwrap());
+        assert!(image.check(false).0.dirty);
+
+        // A repair that leaves nothing behind marks it clean
+        let (report, after) = image.check(true);
+        assert!(report.dirty && report.problems.is_empty());
+        assert_eq!(after.fat(0, 1), FAT_END);
+        let (disk, _) = after.disk();
+        assert!(!is_dirty(disk, &after.0[..SECTOR_SIZE]).unwrap());
+    }
+
+    #[test]
+    fn test_check_without_repair_writes_nothing() {
+        let mut image = Image::sample();
+        image.file(ROOT, b"SHARED  TXT", &[], 0);
+        image.chain(&[20, 21]);
+        image.set_fat(6, 0);
+        image.set_free_count(7);
+        let (report, after) = image.check(false);
+        assert!(report.problems.len() >= 3);
+        assert!(report.problems.iter().all(|problem| !problem.fixed));
+        assert_eq!(report.unfixed(), report.problems.len());
+        assert!(after.0 == image.0);
+    }
+
+    #[test]
+    fn test_cross_links() {
+        let mut image = Image::sample();
+        // Starts in README.TXT's cluster
+        let shared = image.entry(ROOT, b"SHARED  TXT", ATTR_ARCHIVE, 3, 10);
+        // Runs into README.TXT's chain
+        image.chain(&[7, 3]);
+        let joined = image.entry(ROOT, b"JOINED  TXT", ATTR_ARCHIVE, 7, 1024);
+        image.set_free_count(94);
+
+        let (report, after) = image.check(true);
+        assert!(has(&report, "\\SHARED.TXT: first cluster 3 is already in use"));
+        assert!(has(&report, "\\JOINED.TXT: cluster 7 links to cluster 3, which is already in use"));
+        assert!(has(&report, "\\JOINED.TXT: size is 1024 bytes but only 512 are allocated"));
+        assert_eq!(report.unfixed(), 0);
+        // The entry left with nothing, the chain cut before the link
+        assert_eq!((after.first_cluster(shared), after.size(shared)), (0, 0));
+        assert_eq!(after.fat(0, 7), FAT_END);
+        assert_eq!((after.first_cluster(joined), after.size(joined)), (7, 512));
+        assert_eq!(after.fat(0, 3), FAT_END);
+        assert!(after.check(false).0.problems.is_empty());
+    }
+
+    #[test]
+    fn test_bad_links_and_loops() {
+        let mut image = Image::new();
+        image.chain(&[3, 4]);
+        image.set_fat(4, 3);
+        let looped = image.entry(ROOT, b"LOOP       ", ATTR_ARCHIVE, 3, 1024);
+        image.set_fat(5, 50);
+        let dangling = image.entry(ROOT, b"DANGLING   ", ATTR_ARCHIVE, 5, 512);
+        image.set_fat(6, FAT_BAD);
+        let bad = image.entry(ROOT, b"BAD        ", ATTR_ARCHIVE, 6, 512);
+        let outside = image.entry(ROOT, b"OUTSIDE    ", ATTR_ARCHIVE, 500, 512);
+
+        let (report, after) = image.check(true);
+        assert!(has(&report, "\\LOOP: cluster 4 links to cluster 3, which is already in use"));
+        assert!(has(&report, "\\DANGLING: cluster 5 links to 0x32, which is not a cluster in use"));
+        assert!(has(&report, "\\BAD: first cluster 6 is not in use"));
+        assert!(has(&report, "\\OUTSIDE: first cluster 500 is not a cluster"));
+        assert_eq!(after.fat(0, 4), FAT_END);
+        assert_eq!(after.fat(0, 5), FAT_END);
+        assert_eq!(after.first_cluster(looped), 3);
+        assert_eq!(after.size(dangling), 512);
+        // Free and bad clusters stay as they are
+        assert_eq!((after.fat(0, 50), after.fat(0, 6)), (FAT_FREE, FAT_BAD));
+        assert_eq!(after.first_cluster(bad), 0);
+        assert_eq!((after.first_cluster(outside), after.size(outside)), (0, 0));
+        assert!(after.check(false).0.problems.is_empty());
+    }
+
+    #[test]
+    fn test_file_sizes() {
+        let mut image = Image::new();
+        let long = image.file(ROOT, b"LONG       ", &[3, 4, 5], 100);
+        let empty = image.file(ROOT, b"EMPTY      ", &[6], 0);
+        let short = image.file(ROOT, b"SHORT      ", &[7], 2000);
+
+        let (report, after) = image.check(true);
+        assert!(has(&report, "\\LONG: 2 clusters are allocated past the end of the file"));
+        assert!(has(&report, "\\EMPTY: 1 clusters are allocated past the end of the file"));
+        assert!(has(&report, "\\SHORT: size is 2000 bytes but only 512 are allocated"));
+        assert_eq!((after.fat(0, 3), after.fat(0, 4), after.fat(0, 5)), (FAT_END, FAT_FREE, FAT_FREE));
+        assert_eq!(after.size(long), 100);
+        assert_eq!((after.first_cluster(empty), after.fat(0, 6)), (0, FAT_FREE));
+        assert_eq!(after.size(short), 512);
+        assert!(after.check(false).0.problems.is_empty());
+    }
+
+    #[test]
+    fn test_lost_clusters() {
+        let mut image = Image::sample();
+        image.file(ROOT, b"FILE0000CHK", &[8], 512);
+        image.chain(&[20, 21]);
+        image.chain(&[30]);
+        // A loop no chain leads into
+        image.chain(&[40, 41]);
+        image.set_fat(41, 40);
+
+        let (report, after) = image.check(true);
+        assert!(has(&report, "5 lost clusters in 2 chains"));
+        assert_eq!(report.unfixed(), 0);
+        let root = after.cluster(ROOT);
+        let saved: Vec<(&[u8], u32, u32)> = root
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .filter(|entry| entry[0] != ENTRY_END)
+            .map(|entry| (&entry[..11], (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32, u32_at(entry, 28)))
+            .filter(|(name, _, _)| name.ends_with(b"CHK"))
+            .collect();
+        // Named past the one already there
+        assert_eq!(saved, [(&b"FILE0000CHK"[..], 8, 512), (&b"FILE0001CHK"[..], 20, 1024), (&b"FILE0002CHK"[..], 30, 512)]);
+        assert_eq!((after.fat(0, 20), after.fat(0, 21)), (21, FAT_END));
+        assert_eq!((after.fat(0, 40), after.fat(0, 41)), (FAT_FREE, FAT_FREE));
+        assert!(after.check(false).0.problems.is_empty());
+    }
+
+    #[test]
+    fn test_directory_entries() {
+        let mut image = Image::sample();
+        // "." and ".." of DOCS pointing elsewhere
+        let docs = (DATA_START + 4 - 2) * SECTOR_SIZE;
+        set_u16(&mut image.0, docs + 26, 9);
+        set_u16(&mut image.0, docs + DIR_ENTRY_SIZE + 26, 3);
+        let sized = image.directory(ROOT, b"SIZED      ", 10);
+        set_u32(&mut image.0, sized + 28, 4096);
+        let hollow = image.entry(ROOT, b"HOLLOW     ", ATTR_DIRECTORY, 0, 0);
+        image.file(ROOT, b"lower   txt", &[11], 1);
+        // Long names and the volume label are passed over
+        image.entry(ROOT, b"Ab\0c\0d\0e\0f\0", ATTR_LONG_NAME, 0, 0);
+        image.entry(ROOT, b"VOLUME     ", ATTR_VOLUME_ID, 0, 0);
+        image.set_free_count(93);
+
+        let (report, after) = image.check(true);
+        assert!(has(&report, "\\DOCS: \".\" names cluster 9, not 4"));
+        assert!(has(&report, "\\DOCS: \"..\" names cluster 3, not 0"));
+        assert!(has(&report, "\\SIZED: directory has a size of 4096 bytes"));
+        assert!(has(&report, "\\HOLLOW: directory has no clusters"));
+        assert!(has(&report, "\\lower.txt: invalid short name"));
+        assert_eq!(report.problems.len(), 5);
+        assert_eq!(report.unfixed(), 1);
+        assert_eq!((after.first_cluster(docs), after.first_cluster(docs + DIR_ENTRY_SIZE)), (4, 0));
+        assert_eq!(after.size(sized), 0);
+        assert_eq!(after.0[hollow], ENTRY_DELETED);
+        // A problem is left, so the volume is not marked clean
+        assert_eq!(texts(&after.check(false).0), ["\\lower.txt: invalid short name"]);
+    }
+
+    #[test]
+    fn test_directory_cross_link() {
+        let mut image = Image::sample();
+        let again = image.entry(ROOT, b"AGAIN      ", ATTR_DIRECTORY, 4, 0);
+        let (report, after) = image.check(true);
+        assert!(has(&report, "\\AGAIN: first cluster 4 is already in use"));
+        assert_eq!(after.0[again], ENTRY_DELETED);
+        assert_eq!(report.directories, 1);
+    }
+
+    #[test]
+    fn test_fat_copies() {
+        let mut image = Image::sample();
+        set_u32(&mut image.0, Image::fat_entry(1, 30), 0x1234);
+        let (report, after) = image.check(true);
+        assert_eq!(texts(&report), ["FAT copy 2 differs from the first"]);
+        assert_eq!(after.fat(1, 30), FAT_FREE);
+        assert!(after.0[RESERVED * SECTOR_SIZE..(RESERVED + 1) * SECTOR_SIZE]
+            == after.0[(RESERVED + 1) * SECTOR_SIZE..(RESERVED + 2) * SECTOR_SIZE]);
+
+        // Without mirroring only the active FAT counts
+        set_u16(&mut image.0, 40, NO_MIRRORING);
+        assert!(image.check(true).0.problems.is_empty());
+    }
+
+    #[test]
+    fn test_fs_info() {
+        let mut image = Image::sample();
+        image.set_free_count(12);
+        let (report, after) = image.check(true);
+        assert_eq!(texts(&report), ["FSInfo says 12 clusters are free, not 95"]);
+        assert_eq!(u32_at(&after.0, FS_INFO * SECTOR_SIZE + FSINFO_FREE_COUNT), 95);
+
+        // An unknown count is left alone
+        image.set_free_count(FSINFO_UNKNOWN);
+        assert!(image.check(true).0.problems.is_empty());
+
+        image.0[FS_INFO * SECTOR_SIZE] = 0;
+        let (report, _) = image.check(true);
+        assert_eq!(texts(&report), ["FSInfo sector is not valid"]);
+        assert_eq!(report.unfixed(), 1);
+    }
+
+    #[test]
+    fn test_bad_geometry() {
+        let boot = Image::new().0[..SECTOR_SIZE].to_vec();
+        let with = |offset: usize, bytes: &[u8]| {
+            let mut boot = boot.clone();
+            boot[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Volume::open(0, &boot).err()
+        };
+        assert!(Volume::open(0, &boot).is_ok());
+        assert_eq!(with(13, &[3]), Some("invalid sectors per cluster"));
+        assert_eq!(with(16, &[0]), Some("invalid reserved sectors or FAT count"));
+        assert_eq!(with(36, &1000u32.to_le_bytes()), Some("FATs run past the volume"));
+        assert_eq!(with(32, &1000u32.to_le_bytes()), Some("FAT is too small for the volume"));
+        assert_eq!(with(40, &(NO_MIRRORING | 2).to_le_bytes()), Some("active FAT does not exist"));
+        assert_eq!(with(44, &0u32.to_le_bytes()), Some("invalid root directory cluster"));
+        assert_eq!(with(44, &102u32.to_le_bytes()), Some("invalid root directory cluster"));
+
+        // FAT12/16 boot sectors are not taken for FAT32
+        let mut fat16 = boot.clone();
+        set_u16(&mut fat16, 22, 8);
+        assert!(!is_fat32(&fat16));
+        let mut unsigned = boot;
+        unsigned[511] = 0;
+        assert!(!is_fat32(&unsigned));
+    }
+
+    #[test]
+    fn test_short_names() {
+        assert_eq!(short_name(b"README  TXT"), "README.TXT");
+        assert_eq!(short_name(b"MAKEFILE   "), "MAKEFILE");
+        assert_eq!(short_name(b"\x05BC     DAT"), "?BC.DAT");
+        assert!(valid_short_name(b"README  TXT"));
+        assert!(valid_short_name(b"\x05BC     DAT"));
+        assert!(!valid_short_name(b" README TXT"));
+        assert!(!valid_short_name(b"READ*ME TXT"));
+        assert!(!valid_short_name(b"READ\x01ME TXT"));
+        assert_eq!(join("\\", "A"), "\\A");
+        assert_eq!(join("\\A", "B"), "\\A\\B");
+    }
+}
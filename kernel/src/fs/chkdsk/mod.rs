@@ -0,0 +1,161 @@
+//! Consistency checks for FAT32 and NTFS volumes, as chkdsk
+//!
+//! A check reads the volume beneath the file system, through the block
+//! layer, and reports each problem it finds. With repair it fixes what it
+//! can as it goes, and the report says which problems remain. Every check
+//! is logged as a file system event.
+//!
+//! At boot the root disk is checked before it is mounted, as `chkdsk=`
+//! says: `auto` checks and repairs a volume marked dirty, `force` checks
+//! and repairs it every time, and `off` leaves it alone.
+
+mod fat32;
+mod ntfs;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::boot::cmdline::{Param, Value};
+use crate::drivers::block;
+use crate::drivers::disk::SECTOR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCheck {
+    Off,
+    Auto,
+    Force,
+}
+
+impl Value for BootCheck {
+    const KIND: &'static str = "off|auto|force";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "off" => Some(BootCheck::Off),
+            "auto" => Some(BootCheck::Auto),
+            "force" => Some(BootCheck::Force),
+            _ => None,
+        }
+    }
+
+    fn show(&self) -> String {
+        String::from(match self {
+            BootCheck::Off => "off",
+            BootCheck::Auto => "auto",
+            BootCheck::Force => "force",
+        })
+    }
+}
+
+static BOOT_CHECK: Param<BootCheck> =
+    Param::new("chkdsk", BootCheck::Auto, "Check the root volume at boot: off, auto when dirty, or force");
+
+/// Something wrong with a volume, and whether the check fixed it
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub text: String,
+    pub fixed: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub fs_type: &'static str,
+    /// Marked as not cleanly unmounted when the check began
+    pub dirty: bool,
+    pub problems: Vec<Problem>,
+    pub files: u64,
+    pub directories: u64,
+    pub cluster_size: u64,
+    pub clusters: u64,
+    pub free_clusters: u64,
+    pub bad_clusters: u64,
+}
+
+impl Report {
+    fn new(fs_type: &'static str) -> Self {
+        Self { fs_type, ..Self::default() }
+    }
+
+    fn problem(&mut self, fixed: bool, text: String) {
+        self.problems.push(Problem { text, fixed });
+    }
+
+    /// Problems the check left as it found them
+    pub fn unfixed(&self) -> usize {
+        self.problems.iter().filter(|problem| !problem.fixed).count()
+    }
+}
+
+enum Kind {
+    Fat32,
+    Ntfs,
+}
+
+fn boot_sector(disk: usize) -> Result<(Kind, Vec<u8>), &'static str> {
+    let mut boot = vec![0u8; SECTOR_SIZE];
+    block::read(disk, 0, 1, &mut boot).map_err(|_| "cannot read the boot sector")?;
+    if &boot[3..11] == crate::fs::ntfs::NTFS_SIGNATURE {
+        return Ok((Kind::Ntfs, boot));
+    }
+    if fat32::is_fat32(&boot) {
+        return Ok((Kind::Fat32, boot));
+    }
+    Err("not a FAT32 or NTFS volume")
+}
+
+/// The disk the root volume is mounted from
+pub fn root_disk() -> usize {
+    match super::ROOT.get() {
+        super::Root::Disk(index) => index,
+        _ => 0,
+    }
+}
+
+/// Whether `disk` holds the mounted root volume, which a repair would
+/// change under the file system
+pub fn mounted(disk: usize) -> bool {
+    disk == root_disk() && super::vfs::VFS.lock().mounts().iter().any(|(_, fs_type, _)| *fs_type == "vfat")
+}
+
+/// Whether the volume on `disk` was not cleanly unmounted
+pub fn is_dirty(disk: usize) -> Result<bool, &'static str> {
+    match boot_sector(disk)? {
+        (Kind::Fat32, boot) => fat32::is_dirty(disk, &boot),
+        (Kind::Ntfs, boot) => ntfs::is_dirty(disk, &boot),
+    }
+}
+
+/// Check the volume on `disk`, fixing what can be fixed if `repair`
+pub fn check(disk: usize, repair: bool) -> Result<Report, &'static str> {
+    let report = match boot_sector(disk)? {
+        (Kind::Fat32, boot) => fat32::check(disk, &boot, repair)?,
+        (Kind::Ntfs, boot) => ntfs::check(disk, &boot, repair)?,
+    };
+
+    let volume = format!("disk{} ({})", disk, report.fs_type);
+    for problem in &report.problems {
+        crate::pr_warn!("chkdsk: {}: {}{}", volume, problem.text, if problem.fixed { " (fixed)" } else { "" });
+    }
+    let fixed = report.problems.len() - report.unfixed();
+    crate::monitoring::events::emit_fs_checked(&volume, report.problems.len(), fixed);
+    Ok(report)
+}
+
+/// Check the root volume on `disk` before it is mounted, as `chkdsk=` says
+pub fn boot_check(disk: usize) {
+    match BOOT_CHECK.get() {
+        BootCheck::Off => return,
+        BootCheck::Auto if !is_dirty(disk).unwrap_or(false) => return,
+        _ => {}
+    }
+    crate::pr_info!("chkdsk: checking disk{}", disk);
+    match check(disk, true) {
+        Ok(report) if report.problems.is_empty() => crate::pr_info!("chkdsk: disk{} has no problems", disk),
+        Ok(report) => {
+            crate::pr_info!("chkdsk: disk{}: {} problems found, {} left", disk, report.problems.len(), report.unfixed())
+        }
+        Err(reason) => crate::pr_warn!("chkdsk: disk{} not checked: {}", disk, reason),
+    }
+}
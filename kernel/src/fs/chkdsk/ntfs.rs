@@ -0,0 +1,730 @@
+//! NTFS checks
+//!
+//! The MFT is found from the boot sector and read through its own data
+//! runs, a chunk at a time. Each record must pass its update sequence
+//! check and agree with the MFT bitmap about being in use. The clusters
+//! named by the runs of the records in use are claimed, and a cluster
+//! claimed twice is cross-linked. Then:
+//! - each directory's $I30 index entries must name records in use, with
+//!   the sequence number they have now, and the directory as parent
+//! - each file's $FILE_NAME parents must be directories in use
+//! - the claimed clusters must be the ones $Bitmap has in use
+//!
+//! Repair corrects both bitmaps and, when nothing else is left, clears
+//! the dirty flag in $Volume. Damaged records, cross-links and index
+//! problems are only reported.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::Report;
+use crate::drivers::block;
+use crate::drivers::disk::SECTOR_SIZE;
+use crate::fs::ntfs::attributes::{
+    Attribute, AttributeContent, DataRun, ATTR_TYPE_BITMAP, ATTR_TYPE_DATA, ATTR_TYPE_END, ATTR_TYPE_FILE_NAME,
+    ATTR_TYPE_INDEX_ALLOCATION, ATTR_TYPE_INDEX_ROOT, ATTR_TYPE_VOLUME_INFO,
+};
+use crate::fs::ntfs::boot_sector::NtfsBootSector;
+use crate::fs::ntfs::index::IndexEntry;
+use crate::fs::ntfs::mft::MftEntry;
+
+const VOLUME_RECORD: u64 = 3;
+const BITMAP_RECORD: u64 = 6;
+const BAD_CLUSTERS_RECORD: u64 = 8;
+// Reserved records may be marked in use while free
+const FIRST_USER_RECORD: u64 = 24;
+
+const REFERENCE_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
+// $VOLUME_INFORMATION flags, and where they are in its value
+const VOLUME_FLAGS: usize = 10;
+const VOLUME_IS_DIRTY: u16 = 0x0001;
+const INDEX_NAME: &str = "$I30";
+const INDEX_ENTRY_LAST: u16 = 0x0002;
+// Index nodes' headers, in $INDEX_ROOT's value and in INDX blocks
+const ROOT_NODE: usize = 16;
+const BLOCK_NODE: usize = 0x18;
+
+// The MFT is read this many clusters at a time
+const CHUNK_CLUSTERS: u64 = 64;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn bit(map: &[u8], index: u64) -> bool {
+    map.get((index / 8) as usize).is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+}
+
+fn set_bit(map: &mut [u8], index: u64, value: bool) {
+    if let Some(byte) = map.get_mut((index / 8) as usize) {
+        if value {
+            *byte |= 1 << (index % 8);
+        } else {
+            *byte &= !(1 << (index % 8));
+        }
+    }
+}
+
+// Check a record's update sequence, which the last two bytes of each of
+// its sectors must hold, and put back the bytes it stands in for
+fn apply_fixups(record: &mut [u8]) -> bool {
+    let offset = u16_at(record, 4) as usize;
+    let count = u16_at(record, 6) as usize;
+    if count != record.len() / SECTOR_SIZE + 1 || offset + count * 2 > record.len() {
+        return false;
+    }
+    for index in 1..count {
+        let end = index * SECTOR_SIZE - 2;
+        if record[end..end + 2] != record[offset..offset + 2] {
+            return false;
+        }
+        record.copy_within(offset + index * 2..offset + index * 2 + 2, end);
+    }
+    true
+}
+
+// The reverse, with the next update sequence number, before a record is
+// written
+fn redo_fixups(record: &mut [u8]) {
+    let offset = u16_at(record, 4) as usize;
+    let count = u16_at(record, 6) as usize;
+    let number = u16_at(record, offset).wrapping_add(1).max(1);
+    record[offset..offset + 2].copy_from_slice(&number.to_le_bytes());
+    for index in 1..count {
+        let end = index * SECTOR_SIZE - 2;
+        record.copy_within(end..end + 2, offset + index * 2);
+        record[end..end + 2].copy_from_slice(&number.to_le_bytes());
+    }
+}
+
+// Where the value of a resident attribute of type `type_code` starts in
+// a record
+fn resident_value(record: &[u8], type_code: u32) -> Option<usize> {
+    let mut offset = u16_at(record, 20) as usize;
+    while offset + 24 <= record.len() {
+        let kind = u32_at(record, offset);
+        let length = u32_at(record, offset + 4) as usize;
+        if kind == ATTR_TYPE_END || length == 0 {
+            return None;
+        }
+        if kind == type_code && record[offset + 8] == 0 {
+            return Some(offset + u16_at(record, offset + 20) as usize);
+        }
+        offset += length;
+    }
+    None
+}
+
+fn unnamed(entry: &MftEntry, type_code: u32) -> Option<&Attribute> {
+    entry.attributes.iter().find(|attribute| attribute.type_code == type_code && attribute.name.is_empty())
+}
+
+fn named<'a>(entry: &'a MftEntry, type_code: u32, name: &str) -> Option<&'a Attribute> {
+    entry.attributes.iter().find(|attribute| attribute.type_code == type_code && attribute.name == name)
+}
+
+// A piece of a stream: where it starts in the clusters asked for, where
+// it is on the volume, none if sparse, and its length, all in clusters
+struct Extent {
+    offset: u64,
+    lcn: Option<u64>,
+    length: u64,
+}
+
+struct Volume {
+    disk: usize,
+    sectors_per_cluster: u64,
+    cluster_size: u64,
+    clusters: u64,
+    record_size: usize,
+    index_block_size: usize,
+    // From record 0, which describes the MFT
+    mft: Vec<DataRun>,
+    mft_bitmap: Attribute,
+    records: u64,
+}
+
+impl Volume {
+    fn open(disk: usize, boot: &[u8]) -> Result<Self, &'static str> {
+        let boot = NtfsBootSector::parse(boot)?;
+        if boot.bytes_per_sector as usize != SECTOR_SIZE {
+            return Err("sector size is not 512 bytes");
+        }
+        if !boot.sectors_per_cluster.is_power_of_two() {
+            return Err("invalid sectors per cluster");
+        }
+        let record_size = boot.get_mft_record_size() as usize;
+        let index_block_size = boot.get_index_block_size() as usize;
+        for size in [record_size, index_block_size] {
+            if !size.is_power_of_two() || !(SECTOR_SIZE..=65536).contains(&size) {
+                return Err("invalid record or index block size");
+            }
+        }
+        let sectors_per_cluster = boot.sectors_per_cluster as u64;
+        let clusters = boot.total_sectors / sectors_per_cluster;
+        if boot.mft_lcn >= clusters {
+            return Err("$MFT lies past the end of the volume");
+        }
+
+        let mut record = vec![0u8; record_size];
+        let sector = boot.mft_lcn * sectors_per_cluster;
+        block::read(disk, sector, (record_size / SECTOR_SIZE) as u32, &mut record).map_err(|_| "cannot read $MFT")?;
+        if !apply_fixups(&mut record) {
+            return Err("$MFT record is damaged");
+        }
+        let entry = MftEntry::parse(&record)?;
+        let data = match unnamed(&entry, ATTR_TYPE_DATA).map(|attribute| &attribute.content) {
+            Some(AttributeContent::NonResident(data)) => data,
+            _ => return Err("$MFT has no data"),
+        };
+        let cluster_size = sectors_per_cluster * SECTOR_SIZE as u64;
+        // The rest would be in extension records, through an attribute list
+        if data.start_vcn != 0 || (data.last_vcn + 1) * cluster_size < data.real_size {
+            return Err("$MFT is too fragmented to check");
+        }
+        let mft_bitmap = unnamed(&entry, ATTR_TYPE_BITMAP).ok_or("$MFT has no bitmap")?.clone();
+        Ok(Self {
+            disk,
+            sectors_per_cluster,
+            cluster_size,
+            clusters,
+            record_size,
+            index_block_size,
+            mft: data.data_runs.clone(),
+            mft_bitmap,
+            records: data.real_size / record_size as u64,
+        })
+    }
+
+    // The extents of `count` clusters from `vcn` on, in the stream `runs`
+    // describe
+    fn extents(&self, runs: &[DataRun], vcn: u64, count: u64) -> Result<Vec<Extent>, &'static str> {
+        let mut extents = Vec::new();
+        let mut start = 0;
+        for run in runs {
+            let from = vcn.max(start);
+            let to = (vcn + count).min(start + run.length);
+            if from < to {
+                let lcn = (!run.sparse).then(|| run.start_lcn + (from - start));
+                if lcn.is_some_and(|lcn| lcn + (to - from) > self.clusters) {
+                    return Err("a data run lies past the end of the volume");
+                }
+                extents.push(Extent { offset: from - vcn, lcn, length: to - from });
+            }
+            start += run.length;
+        }
+        Ok(extents)
+    }
+
+    // Clusters past the end of the runs, and sparse ones, read as zeros
+    fn read_vcns(&self, runs: &[DataRun], vcn: u64, count: u64) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; (count * self.cluster_size) as usize];
+        for extent in self.extents(runs, vcn, count)? {
+            let Some(lcn) = extent.lcn else { continue };
+            let start = (extent.offset * self.cluster_size) as usize;
+            let bytes = &mut data[start..start + (extent.length * self.cluster_size) as usize];
+            let sectors = (extent.length * self.sectors_per_cluster) as u32;
+            block::read(self.disk, lcn * self.sectors_per_cluster, sectors, bytes)
+                .map_err(|_| "cannot read the volume")?;
+        }
+        Ok(data)
+    }
+
+    fn write_vcns(&self, runs: &[DataRun], vcn: u64, data: &[u8]) -> Result<(), &'static str> {
+        let count = data.len() as u64 / self.cluster_size;
+        for extent in self.extents(runs, vcn, count)? {
+            let lcn = extent.lcn.ok_or("cannot write a sparse run")?;
+            let start = (extent.offset * self.cluster_size) as usize;
+            let bytes = &data[start..start + (extent.length * self.cluster_size) as usize];
+            let sectors = (extent.length * self.sectors_per_cluster) as u32;
+            block::write(self.disk, lcn * self.sectors_per_cluster, sectors, bytes)
+                .map_err(|_| "cannot write the volume")?;
+        }
+        Ok(())
+    }
+
+    fn read_stream(&self, attribute: &Attribute) -> Result<Vec<u8>, &'static str> {
+        match &attribute.content {
+            AttributeContent::Resident(value) => Ok(value.clone()),
+            AttributeContent::NonResident(data) => {
+                let count = data.real_size.div_ceil(self.cluster_size);
+                let mut stream = self.read_vcns(&data.data_runs, 0, count)?;
+                stream.truncate(data.real_size as usize);
+                Ok(stream)
+            }
+        }
+    }
+
+    // Write back the clusters of a non-resident stream that differ from
+    // `old`
+    fn write_stream(&self, attribute: &Attribute, old: &[u8], new: &[u8]) -> Result<(), &'static str> {
+        let AttributeContent::NonResident(data) = &attribute.content else {
+            return Err("cannot write a resident bitmap");
+        };
+        let cluster_size = self.cluster_size as usize;
+        for (vcn, (old, new)) in old.chunks(cluster_size).zip(new.chunks(cluster_size)).enumerate() {
+            if old != new {
+                let mut cluster = vec![0u8; cluster_size];
+                cluster[..new.len()].copy_from_slice(new);
+                self.write_vcns(&data.data_runs, vcn as u64, &cluster)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The clusters holding record `number`, and where it starts in them
+    fn record_clusters(&self, number: u64) -> (u64, u64, usize) {
+        let offset = number * self.record_size as u64;
+        let count = (self.record_size as u64).div_ceil(self.cluster_size);
+        (offset / self.cluster_size, count, (offset % self.cluster_size) as usize)
+    }
+
+    fn read_record(&self, number: u64) -> Result<Vec<u8>, &'static str> {
+        let (vcn, count, start) = self.record_clusters(number);
+        let data = self.read_vcns(&self.mft, vcn, count)?;
+        let mut record = data[start..start + self.record_size].to_vec();
+        if !apply_fixups(&mut record) {
+            return Err("record is damaged");
+        }
+        Ok(record)
+    }
+
+    fn write_record(&self, number: u64, mut record: Vec<u8>) -> Result<(), &'static str> {
+        redo_fixups(&mut record);
+        let (vcn, count, start) = self.record_clusters(number);
+        let mut data = self.read_vcns(&self.mft, vcn, count)?;
+        data[start..start + self.record_size].copy_from_slice(&record);
+        self.write_vcns(&self.mft, vcn, &data)
+    }
+
+    fn volume_flags(&self) -> Result<(Vec<u8>, usize), &'static str> {
+        let record = self.read_record(VOLUME_RECORD)?;
+        let offset = resident_value(&record, ATTR_TYPE_VOLUME_INFO).ok_or("$Volume has no information")?;
+        if offset + VOLUME_FLAGS + 2 > record.len() {
+            return Err("$Volume is damaged");
+        }
+        Ok((record, offset + VOLUME_FLAGS))
+    }
+}
+
+/// Whether $Volume says the volume on `disk` was not cleanly unmounted
+pub(super) fn is_dirty(disk: usize, boot: &[u8]) -> Result<bool, &'static str> {
+    let (record, flags) = Volume::open(disk, boot)?.volume_flags()?;
+    Ok(u16_at(&record, flags) & VOLUME_IS_DIRTY != 0)
+}
+
+// An index entry, to be checked once every record has been seen
+struct IndexReference {
+    directory: u64,
+    reference: u64,
+    parent: u64,
+}
+
+struct Checker {
+    volume: Volume,
+    repair: bool,
+    report: Report,
+    mft_bitmap: Vec<u8>,
+    // Bitmaps of clusters claimed, base records in use and directories
+    claimed: Vec<u8>,
+    in_use: Vec<u8>,
+    directories: Vec<u8>,
+    sequence: Vec<u16>,
+    index: Vec<IndexReference>,
+    // Each file name's record, and its parent reference
+    parents: Vec<(u64, u64)>,
+    bitmap: Option<Attribute>,
+}
+
+impl Checker {
+    fn records(&mut self) -> Result<(), &'static str> {
+        let record_size = self.volume.record_size as u64;
+        let chunk = CHUNK_CLUSTERS.max(record_size / self.volume.cluster_size);
+        let total = (self.volume.records * record_size).div_ceil(self.volume.cluster_size);
+        let runs = self.volume.mft.clone();
+        let mut number = 0;
+        for vcn in (0..total).step_by(chunk as usize) {
+            let data = self.volume.read_vcns(&runs, vcn, chunk.min(total - vcn))?;
+            for raw in data.chunks_exact(record_size as usize) {
+                if number >= self.volume.records {
+                    break;
+                }
+                self.record(number, raw.to_vec());
+                number += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, number: u64, mut raw: Vec<u8>) {
+        let marked = bit(&self.mft_bitmap, number);
+        if &raw[0..4] != b"FILE" {
+            if &raw[0..4] == b"BAAD" {
+                self.report.problem(false, format!("record {} is marked bad", number));
+            } else if marked && number >= FIRST_USER_RECORD {
+                self.report.problem(self.repair, format!("record {} is marked in use but is not a file", number));
+                set_bit(&mut self.mft_bitmap, number, false);
+            }
+            return;
+        }
+        if !apply_fixups(&mut raw) {
+            self.report.problem(false, format!("record {} failed its update sequence check", number));
+            return;
+        }
+        let entry = match MftEntry::parse(&raw) {
+            Ok(entry) => entry,
+            Err(reason) => {
+                self.report.problem(false, format!("record {}: {}", number, reason));
+                return;
+            }
+        };
+
+        if entry.is_in_use() && !marked {
+            self.report.problem(self.repair, format!("record {} is in use but free in the MFT bitmap", number));
+            set_bit(&mut self.mft_bitmap, number, true);
+        } else if !entry.is_in_use() && marked && number >= FIRST_USER_RECORD {
+            self.report.problem(self.repair, format!("record {} is free but marked in use in the MFT bitmap", number));
+            set_bit(&mut self.mft_bitmap, number, false);
+        }
+        if !entry.is_in_use() {
+            return;
+        }
+        let said = entry.header.record_number as u64;
+        if said != 0 && said != number {
+            self.report.problem(false, format!("record {} says it is record {}", number, said));
+        }
+
+        let base = entry.header.file_reference & REFERENCE_MASK;
+        if base == 0 {
+            set_bit(&mut self.in_use, number, true);
+            self.sequence[number as usize] = entry.header.sequence_number;
+            if entry.is_directory() {
+                self.report.directories += 1;
+                set_bit(&mut self.directories, number, true);
+            } else {
+                self.report.files += 1;
+            }
+            for attribute in entry.attributes.iter().filter(|attribute| attribute.type_code == ATTR_TYPE_FILE_NAME) {
+                if let AttributeContent::Resident(value) = &attribute.content {
+                    if value.len() >= 8 {
+                        self.parents.push((number, u64_at(value, 0)));
+                    }
+                }
+            }
+            if number == BITMAP_RECORD {
+                self.bitmap = unnamed(&entry, ATTR_TYPE_DATA).cloned();
+            }
+        }
+        self.index_entries(if base == 0 { number } else { base }, &entry);
+        self.claim(number, &entry);
+    }
+
+    fn claim(&mut self, number: u64, entry: &MftEntry) {
+        let mut outside = false;
+        let mut shared = 0u64;
+        for attribute in &entry.attributes {
+            let AttributeContent::NonResident(data) = &attribute.content else { continue };
+            for run in data.data_runs.iter().filter(|run| !run.sparse) {
+                if run.start_lcn + run.length > self.volume.clusters {
+                    outside = true;
+                    continue;
+                }
+                for cluster in run.start_lcn..run.start_lcn + run.length {
+                    if bit(&self.claimed, cluster) {
+                        shared += 1;
+                    }
+                    set_bit(&mut self.claimed, cluster, true);
+                }
+                if number == BAD_CLUSTERS_RECORD && attribute.name == "$Bad" {
+                    self.report.bad_clusters += run.length;
+                }
+            }
+        }
+        if outside {
+            self.report.problem(false, format!("record {} has data past the end of the volume", number));
+        }
+        if shared > 0 {
+            self.report.problem(false, format!("record {} shares {} clusters with other records", number, shared));
+        }
+    }
+
+    // Gather the entries of one index node, from `start` to `end` of `node`
+    fn node(&mut self, directory: u64, node: &[u8], start: usize, end: usize) -> bool {
+        let end = end.min(node.len());
+        let mut offset = start;
+        while offset + 16 <= end {
+            let Ok(entry) = IndexEntry::parse(&node[offset..end]) else { return false };
+            if entry.flags & INDEX_ENTRY_LAST != 0 {
+                return true;
+            }
+            if (entry.length as usize) < 16 {
+                return false;
+            }
+            if entry.key.len() >= 8 {
+                let parent = u64_at(&entry.key, 0);
+                self.index.push(IndexReference { directory, reference: entry.file_reference, parent });
+            }
+            offset += entry.length as usize;
+        }
+        false
+    }
+
+    fn index_entries(&mut self, directory: u64, entry: &MftEntry) {
+        if let Some(AttributeContent::Resident(root)) =
+            named(entry, ATTR_TYPE_INDEX_ROOT, INDEX_NAME).map(|attribute| &attribute.content)
+        {
+            let intact = root.len() >= ROOT_NODE + 8 && {
+                let start = ROOT_NODE + u32_at(root, ROOT_NODE) as usize;
+                let end = ROOT_NODE + u32_at(root, ROOT_NODE + 4) as usize;
+                self.node(directory, root, start, end)
+            };
+            if !intact {
+                self.report.problem(false, format!("directory {}: index root is damaged", directory));
+            }
+        }
+
+        // Blocks the index's bitmap has free may hold anything
+        let Some(allocation) = named(entry, ATTR_TYPE_INDEX_ALLOCATION, INDEX_NAME) else { return };
+        let Some(used) = named(entry, ATTR_TYPE_BITMAP, INDEX_NAME) else { return };
+        let (Ok(blocks), Ok(used)) = (self.volume.read_stream(allocation), self.volume.read_stream(used)) else {
+            self.report.problem(false, format!("directory {}: cannot read its index", directory));
+            return;
+        };
+        let mut damaged = 0;
+        for (number, block) in blocks.chunks_exact(self.volume.index_block_size).enumerate() {
+            if !bit(&used, number as u64) {
+                continue;
+            }
+            let mut block = block.to_vec();
+            let intact = &block[0..4] == b"INDX" && apply_fixups(&mut block) && {
+                let start = BLOCK_NODE + u32_at(&block, BLOCK_NODE) as usize;
+                let end = BLOCK_NODE + u32_at(&block, BLOCK_NODE + 4) as usize;
+                self.node(directory, &block, start, end)
+            };
+            if !intact {
+                damaged += 1;
+            }
+        }
+        if damaged > 0 {
+            self.report.problem(false, format!("directory {}: {} index blocks are damaged", directory, damaged));
+        }
+    }
+
+    fn in_use(&self, reference: u64) -> Result<u64, String> {
+        let record = reference & REFERENCE_MASK;
+        let sequence = (reference >> 48) as u16;
+        if !bit(&self.in_use, record) {
+            return Err(format!("record {}, which is not in use", record));
+        }
+        let current = self.sequence[record as usize];
+        if sequence != 0 && sequence != current {
+            return Err(format!("record {} with sequence number {}, not {}", record, sequence, current));
+        }
+        Ok(record)
+    }
+
+    fn check_index(&mut self) {
+        for entry in core::mem::take(&mut self.index) {
+            let problem = match self.in_use(entry.reference) {
+                Err(reason) => format!("directory {}: entry for {}", entry.directory, reason),
+                Ok(record) if entry.parent & REFERENCE_MASK != entry.directory => format!(
+                    "directory {}: entry for record {} names record {} as its parent",
+                    entry.directory,
+                    record,
+                    entry.parent & REFERENCE_MASK
+                ),
+                Ok(_) => continue,
+            };
+            self.report.problem(false, problem);
+        }
+    }
+
+    fn check_parents(&mut self) {
+        for (record, parent) in core::mem::take(&mut self.parents) {
+            let problem = match self.in_use(parent) {
+                Err(reason) => format!("record {}: parent is {}", record, reason),
+                Ok(parent) if !bit(&self.directories, parent) => {
+                    format!("record {}: parent record {} is not a directory", record, parent)
+                }
+                Ok(_) => continue,
+            };
+            self.report.problem(false, problem);
+        }
+    }
+
+    // Compare the claimed clusters with $Bitmap, and the MFT bitmap with
+    // what the records said; with repair, write back what was corrected
+    fn bitmaps(&mut self, mft_bitmap: &[u8]) -> Result<(), &'static str> {
+        let attribute = self.bitmap.take().ok_or("$Bitmap has no data")?;
+        let old = self.volume.read_stream(&attribute)?;
+        let mut new = old.clone();
+        let (mut unmarked, mut unclaimed, mut used) = (0u64, 0u64, 0u64);
+        for cluster in 0..self.volume.clusters {
+            let claimed = bit(&self.claimed, cluster);
+            let marked = bit(&old, cluster);
+            if claimed && !marked {
+                unmarked += 1;
+            } else if !claimed && marked {
+                unclaimed += 1;
+            }
+            used += claimed as u64;
+            set_bit(&mut new, cluster, claimed);
+        }
+        self.report.free_clusters = self.volume.clusters - used;
+        if unmarked > 0 {
+            self.report.problem(self.repair, format!("{} clusters in use are free in $Bitmap", unmarked));
+        }
+        if unclaimed > 0 {
+            self.report
+                .problem(self.repair, format!("{} clusters marked in use in $Bitmap belong to nothing", unclaimed));
+        }
+        if self.repair {
+            self.volume.write_stream(&attribute, &old, &new)?;
+            self.volume.write_stream(&self.volume.mft_bitmap, mft_bitmap, &self.mft_bitmap)?;
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn check(disk: usize, boot: &[u8], repair: bool) -> Result<Report, &'static str> {
+    let volume = Volume::open(disk, boot)?;
+    let mut report = Report::new("NTFS");
+    report.cluster_size = volume.cluster_size;
+    report.clusters = volume.clusters;
+    let (mut record, flags) = volume.volume_flags()?;
+    report.dirty = u16_at(&record, flags) & VOLUME_IS_DIRTY != 0;
+
+    let mft_bitmap = volume.read_stream(&volume.mft_bitmap)?;
+    let records = volume.records as usize;
+    let mut checker = Checker {
+        mft_bitmap: mft_bitmap.clone(),
+        claimed: vec![0u8; volume.clusters.div_ceil(8) as usize],
+        in_use: vec![0u8; records.div_ceil(8)],
+        directories: vec![0u8; records.div_ceil(8)],
+        sequence: vec![0u16; records],
+        index: Vec::new(),
+        parents: Vec::new(),
+        bitmap: None,
+        volume,
+        repair,
+        report,
+    };
+    checker.records()?;
+    checker.check_index();
+    checker.check_parents();
+    checker.bitmaps(&mft_bitmap)?;
+
+    // Only once nothing is left that a check at mount should look at again
+    if repair && checker.report.dirty && checker.report.unfixed() == 0 {
+        let cleared = u16_at(&record, flags) & !VOLUME_IS_DIRTY;
+        record[flags..flags + 2].copy_from_slice(&cleared.to_le_bytes());
+        checker.volume.write_record(VOLUME_RECORD, record)?;
+    }
+    Ok(checker.report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A two sector record with update sequence number 7 in place
+    fn fixed_up() -> Vec<u8> {
+        let mut record = vec![0u8; 2 * SECTOR_SIZE];
+        record[4..6].copy_from_slice(&48u16.to_le_bytes());
+        record[6..8].copy_from_slice(&3u16.to_le_bytes());
+        record[48..50].copy_from_slice(&7u16.to_le_bytes());
+        record[50..52].copy_from_slice(&[0xAA, 0xBB]);
+        record[52..54].copy_from_slice(&[0xCC, 0xDD]);
+        for end in [SECTOR_SIZE - 2, 2 * SECTOR_SIZE - 2] {
+            record[end..end + 2].copy_from_slice(&7u16.to_le_bytes());
+        }
+        record
+    }
+
+    #[test]
+    fn test_fixups() {
+        let mut record = fixed_up();
+        assert!(apply_fixups(&mut record));
+        assert_eq!(record[SECTOR_SIZE - 2..SECTOR_SIZE], [0xAA, 0xBB]);
+        assert_eq!(record[2 * SECTOR_SIZE - 2..], [0xCC, 0xDD]);
+
+        redo_fixups(&mut record);
+        assert_eq!(u16_at(&record, 48), 8);
+        assert_eq!(u16_at(&record, SECTOR_SIZE - 2), 8);
+        assert_eq!(record[50..54], [0xAA, 0xBB, 0xCC, 0xDD]);
+        assert!(apply_fixups(&mut record));
+        assert_eq!(record[2 * SECTOR_SIZE - 2..], [0xCC, 0xDD]);
+    }
+
+    #[test]
+    fn test_fixups_skip_zero() {
+        let mut record = fixed_up();
+        record[48..50].copy_from_slice(&u16::MAX.to_le_bytes());
+        redo_fixups(&mut record);
+        assert_eq!(u16_at(&record, 48), 1);
+    }
+
+    #[test]
+    fn test_torn_records() {
+        // A sector that wasn't written with the rest
+        let mut record = fixed_up();
+        record[2 * SECTOR_SIZE - 2] = 6;
+        assert!(!apply_fixups(&mut record));
+
+        // An update sequence that doesn't cover every sector
+        let mut record = fixed_up();
+        record[6..8].copy_from_slice(&2u16.to_le_bytes());
+        assert!(!apply_fixups(&mut record));
+
+        // Or runs off the end
+        let mut record = fixed_up();
+        record[4..6].copy_from_slice(&(2 * SECTOR_SIZE as u16 - 4).to_le_bytes());
+        assert!(!apply_fixups(&mut record));
+    }
+
+    #[test]
+    fn test_bits() {
+        let mut map = [0u8; 2];
+        set_bit(&mut map, 9, true);
+        set_bit(&mut map, 0, true);
+        assert_eq!(map, [0x01, 0x02]);
+        assert!(bit(&map, 9) && !bit(&map, 8));
+        set_bit(&mut map, 9, false);
+        assert_eq!(map, [0x01, 0x00]);
+
+        // Past the end of the map reads as clear and isn't written
+        set_bit(&mut map, 16, true);
+        assert!(!bit(&map, 16));
+    }
+
+    #[test]
+    fn test_resident_value() {
+        let mut record = vec![0u8; 1024];
+        record[20..22].copy_from_slice(&56u16.to_le_bytes());
+        // A non-resident attribute of the type asked for is passed over
+        record[56..60].copy_from_slice(&ATTR_TYPE_VOLUME_INFO.to_le_bytes());
+        record[60..64].copy_from_slice(&72u32.to_le_bytes());
+        record[64] = 1;
+        record[128..132].copy_from_slice(&ATTR_TYPE_VOLUME_INFO.to_le_bytes());
+        record[132..136].copy_from_slice(&40u32.to_le_bytes());
+        record[148..150].copy_from_slice(&24u16.to_le_bytes());
+        record[168..172].copy_from_slice(&ATTR_TYPE_END.to_le_bytes());
+
+        assert_eq!(resident_value(&record, ATTR_TYPE_VOLUME_INFO), Some(152));
+        assert_eq!(resident_value(&record, ATTR_TYPE_DATA), None);
+
+        // A zero length attribute ends the walk
+        record[132..136].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(resident_value(&record, ATTR_TYPE_VOLUME_INFO), None);
+    }
+}
@@ -1,10 +1,12 @@
-#![no_std]
-
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::collections::BTreeMap;
+use alloc::format;
 use spin::RwLock;
-use crate::crypto::{CryptoEngine, CipherAlgorithm, AeadAlgorithm, KdfAlgorithm};
+use crate::crypto::{self, CryptoEngine, CipherAlgorithm, AeadAlgorithm, KdfAlgorithm};
+
+pub use crate::crypto::CryptoError;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EncryptionMode {
@@ -173,6 +175,7 @@ impl FilesystemCrypto {
         }
     }
     
+    // IEEE 1619 XTS with the sector number as the tweak
     fn encrypt_xts(
         &self,
         data: &[u8],
@@ -180,38 +183,7 @@ impl FilesystemCrypto {
         key1: &[u8],
         key2: &[u8],
     ) -> Result<Vec<u8>, CryptoError> {
-        if key1.len() != 32 || key2.len() != 32 {
-            return Err(CryptoError::InvalidKeySize);
-        }
-        
-        let cipher = self.crypto_engine.get_cipher(CipherAlgorithm::Aes256)?;
-        let mut ciphertext = Vec::with_capacity(data.len());
-        
-        let mut tweak = [0u8; 16];
-        tweak[..8].copy_from_slice(&sector_num.to_le_bytes());
-        
-        let encrypted_tweak = cipher.encrypt(&tweak, key2, None)?;
-        let mut current_tweak = [0u8; 16];
-        current_tweak.copy_from_slice(&encrypted_tweak[..16]);
-        
-        for chunk in data.chunks(16) {
-            let mut block = [0u8; 16];
-            block[..chunk.len()].copy_from_slice(chunk);
-            
-            for i in 0..16 {
-                block[i] ^= current_tweak[i];
-            }
-            
-            let encrypted = cipher.encrypt(&block, key1, None)?;
-            
-            for i in 0..16 {
-                ciphertext.push(encrypted[i] ^ current_tweak[i]);
-            }
-            
-            self.gf_multiply_128(&mut current_tweak);
-        }
-        
-        Ok(ciphertext)
+        crypto::aes_xts_encrypt(&Self::xts_key(key1, key2)?, sector_num, data)
     }
     
     fn decrypt_xts(
@@ -221,52 +193,18 @@ impl FilesystemCrypto {
         key1: &[u8],
         key2: &[u8],
     ) -> Result<Vec<u8>, CryptoError> {
-        if key1.len() != 32 || key2.len() != 32 {
-            return Err(CryptoError::InvalidKeySize);
-        }
-        
-        let cipher = self.crypto_engine.get_cipher(CipherAlgorithm::Aes256)?;
-        let mut plaintext = Vec::with_capacity(ciphertext.len());
-        
-        let mut tweak = [0u8; 16];
-        tweak[..8].copy_from_slice(&sector_num.to_le_bytes());
-        
-        let encrypted_tweak = cipher.encrypt(&tweak, key2, None)?;
-        let mut current_tweak = [0u8; 16];
-        current_tweak.copy_from_slice(&encrypted_tweak[..16]);
-        
-        for chunk in ciphertext.chunks(16) {
-            let mut block = [0u8; 16];
-            block[..chunk.len()].copy_from_slice(chunk);
-            
-            for i in 0..16 {
-                block[i] ^= current_tweak[i];
-            }
-            
-            let decrypted = cipher.decrypt(&block, key1, None)?;
-            
-            for i in 0..16 {
-                plaintext.push(decrypted[i] ^ current_tweak[i]);
-            }
-            
-            self.gf_multiply_128(&mut current_tweak);
-        }
-        
-        Ok(plaintext)
+        crypto::aes_xts_decrypt(&Self::xts_key(key1, key2)?, sector_num, ciphertext)
     }
     
-    fn gf_multiply_128(&self, block: &mut [u8; 16]) {
-        let mut carry = false;
-        
-        for i in (0..16).rev() {
-            let new_carry = (block[i] & 0x80) != 0;
-            block[i] = (block[i] << 1) | if carry { 1 } else { 0 };
-            carry = new_carry;
+    fn xts_key(key1: &[u8], key2: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if key1.len() != 32 || key2.len() != 32 {
+            return Err(CryptoError::InvalidKeySize);
         }
         
-        if carry {
-            block[0] ^= 0x87;
-        }
+        let mut key = Vec::with_capacity(64);
+        key.extend_from_slice(key1);
+        key.extend_from_slice(key2);
+        Ok(key)
     }
     
     fn generate_salt(&self, inode: u64) -> Vec<u8> {
@@ -417,30 +355,24 @@ impl EncryptedVolume {
         Ok(derived)
     }
     
+    // Random (version 4) UUID
     fn generate_uuid() -> String {
-        String::from("12345678-1234-1234-1234-123456789abc")
+        let mut bytes = [0u8; 16];
+        crypto::random_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
     }
     
     fn generate_salt() -> [u8; 32] {
         let mut salt = [0u8; 32];
-        for i in 0..32 {
-            salt[i] = ((i * 7 + 13) % 256) as u8;
-        }
+        crypto::random_bytes(&mut salt);
         salt
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum CryptoError {
-    InvalidKeySize,
-    InvalidBlockSize,
-    InvalidNonce,
-    AuthenticationFailed,
-    DecryptionFailed,
-    UnsupportedAlgorithm,
-    InvalidParameter,
-}
-
 pub struct DmCrypt {
     volumes: RwLock<BTreeMap<String, EncryptedVolume>>,
     crypto: FilesystemCrypto,
@@ -1,7 +1,9 @@
 // FAT32 File System Implementation
-use super::{FileSystem, FileSystemError, FileInfo, FileType};
-use alloc::{vec::{self, Vec}, string::String};
-use crate::drivers::disk::{DiskDriver, DISK_MANAGER, SECTOR_SIZE};
+use super::{FileSystem, FileSystemError, FileInfo, FileType, FsStats};
+use super::unicode::{self, UpCase};
+use alloc::{vec::{self, Vec}, string::String, collections::BTreeMap};
+use crate::drivers::block;
+use crate::drivers::disk::{DiskError, SECTOR_SIZE};
 
 // FAT32 constants
 const FAT32_SIGNATURE: u16 = 0xAA55;
@@ -9,6 +11,10 @@ const BYTES_PER_DIR_ENTRY: usize = 32;
 const FAT_ENTRY_SIZE: u32 = 4;
 const END_OF_CLUSTER_CHAIN: u32 = 0x0FFFFFFF;
 const BAD_CLUSTER: u32 = 0x0FFFFFF7;
+// Sectors of the FAT read at a time when counting free clusters
+const FAT_SECTORS_PER_READ: u32 = 64;
+// Files whose cluster chains are kept for ranged reads
+const OPEN_CHAINS: usize = 64;
 
 // FAT32 Boot Sector structure
 #[repr(C, packed)]
@@ -70,6 +76,11 @@ const ATTR_VOLUME_ID: u8 = 0x08;
 const ATTR_DIRECTORY: u8 = 0x10;
 const ATTR_ARCHIVE: u8 = 0x20;
 const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+// The order byte of a long name's final part
+const LAST_LONG_ENTRY: u8 = 0x40;
+// nt_reserved bits for a short name shown in lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXTENSION: u8 = 0x10;
 
 pub struct Fat32FileSystem {
     disk_index: usize,
@@ -78,6 +89,16 @@ pub struct Fat32FileSystem {
     data_start_sector: u32,
     sectors_per_cluster: u32,
     root_dir_cluster: u32,
+    chains: BTreeMap<String, OpenChain>,
+}
+
+// A file being read by range: its size and as much of its cluster chain as
+// reads have needed so far, so each read does not walk the FAT from the
+// start
+struct OpenChain {
+    size: u32,
+    first_cluster: u32,
+    clusters: Vec<u32>,
 }
 
 impl Fat32FileSystem {
@@ -88,21 +109,17 @@ impl Fat32FileSystem {
         let mut boot_sector_data = Vec::with_capacity(SECTOR_SIZE);
         boot_sector_data.resize(SECTOR_SIZE, 0u8);
         
-        {
-            let mut disk_manager = DISK_MANAGER.lock();
-            if let Some(disk) = disk_manager.get_disk(disk_index) {
-                serial_println!("Reading boot sector from disk {}...", disk_index);
-                match disk.read_sectors(0, 1, &mut boot_sector_data) {
-                    Ok(_) => serial_println!("Boot sector read successfully"),
-                    Err(e) => {
-                        serial_println!("Failed to read boot sector: {:?}", e);
-                        return Err(FileSystemError::IoError(String::from("IO error")));
-                    }
-                }
-            } else {
+        serial_println!("Reading boot sector from disk {}...", disk_index);
+        match block::read(disk_index, 0, 1, &mut boot_sector_data) {
+            Ok(_) => serial_println!("Boot sector read successfully"),
+            Err(DiskError::NotFound) => {
                 serial_println!("Disk {} not found", disk_index);
                 return Err(FileSystemError::NotFound);
             }
+            Err(e) => {
+                serial_println!("Failed to read boot sector: {:?}", e);
+                return Err(FileSystemError::IoError(String::from("IO error")));
+            }
         }
         
         // Parse boot sector
@@ -128,6 +145,7 @@ impl Fat32FileSystem {
             data_start_sector,
             sectors_per_cluster: boot_sector.sectors_per_cluster as u32,
             root_dir_cluster: boot_sector.root_cluster,
+            chains: BTreeMap::new(),
         })
     }
     
@@ -142,11 +160,8 @@ impl Fat32FileSystem {
         let mut data = Vec::with_capacity(self.sectors_per_cluster as usize * SECTOR_SIZE);
         data.resize(self.sectors_per_cluster as usize * SECTOR_SIZE, 0u8);
         
-        let mut disk_manager = DISK_MANAGER.lock();
-        if let Some(disk) = disk_manager.get_disk(self.disk_index) {
-            disk.read_sectors(sector as u64, self.sectors_per_cluster, &mut data)
-                .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
-        }
+        block::read(self.disk_index, sector as u64, self.sectors_per_cluster, &mut data)
+            .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
         
         Ok(data)
     }
@@ -160,11 +175,8 @@ impl Fat32FileSystem {
         let mut sector_data = Vec::with_capacity(SECTOR_SIZE);
         sector_data.resize(SECTOR_SIZE, 0u8);
         
-        let mut disk_manager = DISK_MANAGER.lock();
-        if let Some(disk) = disk_manager.get_disk(self.disk_index) {
-            disk.read_sectors(fat_sector as u64, 1, &mut sector_data)
-                .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
-        }
+        block::read(self.disk_index, fat_sector as u64, 1, &mut sector_data)
+            .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
         
         let next_cluster = u32::from_le_bytes([
             sector_data[entry_offset],
@@ -190,120 +202,231 @@ impl Fat32FileSystem {
         Ok(data)
     }
     
-    // Parse short filename (8.3 format)
-    fn parse_short_name(name: &[u8; 11]) -> String {
-        let mut result = String::new();
-        
-        // First 8 bytes are the name
-        for i in 0..8 {
-            if name[i] == 0x20 || name[i] == 0 {
-                break;
+    // The `index`th cluster of a file, following its chain as far as that
+    fn chain_cluster(&self, chain: &mut OpenChain, index: usize) -> Result<u32, FileSystemError> {
+        while chain.clusters.len() <= index {
+            let next = match chain.clusters.last() {
+                Some(&last) => self.get_next_cluster(last)?,
+                None => chain.first_cluster,
+            };
+            if next < 2 || next >= END_OF_CLUSTER_CHAIN || next == BAD_CLUSTER {
+                return Err(FileSystemError::IoError(String::from("Cluster chain shorter than the file")));
+            }
+            chain.clusters.push(next);
+        }
+        Ok(chain.clusters[index])
+    }
+    
+    // Read part of a file a cluster at a time, whole sectors straight into
+    // the buffer
+    fn read_chain(&self, chain: &mut OpenChain, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        let size = chain.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = (size - offset).min(buffer.len() as u64) as usize;
+        let cluster_size = self.sectors_per_cluster as usize * SECTOR_SIZE;
+        
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let within = (position % cluster_size as u64) as usize;
+            let cluster = self.chain_cluster(chain, (position / cluster_size as u64) as usize)?;
+            let count = (cluster_size - within).min(length - done);
+            let out = &mut buffer[done..done + count];
+            
+            if within % SECTOR_SIZE == 0 && count % SECTOR_SIZE == 0 {
+                let sector = self.cluster_to_sector(cluster) as u64 + (within / SECTOR_SIZE) as u64;
+                block::read(self.disk_index, sector, (count / SECTOR_SIZE) as u32, out)
+                    .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
+            } else {
+                out.copy_from_slice(&self.read_cluster(cluster)?[within..within + count]);
             }
-            result.push(name[i] as char);
+            done += count;
         }
         
-        // Last 3 bytes are the extension
-        if name[8] != 0x20 && name[8] != 0 {
-            result.push('.');
-            for i in 8..11 {
-                if name[i] == 0x20 || name[i] == 0 {
-                    break;
-                }
-                result.push(name[i] as char);
+        Ok(length)
+    }
+    
+    // The directory entry of the regular file at `path`
+    fn find_file(&self, path: &str) -> Result<Fat32DirEntry, FileSystemError> {
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let (last, directories) = parts.split_last().ok_or(FileSystemError::InvalidPath)?;
+        
+        let mut current_cluster = self.root_dir_cluster;
+        for part in directories {
+            let entry = self.find_in_directory(current_cluster, part)?;
+            if entry.attributes & ATTR_DIRECTORY == 0 {
+                return Err(FileSystemError::InvalidPath);
             }
+            current_cluster = (entry.first_cluster_high as u32) << 16 | entry.first_cluster_low as u32;
+        }
+        let entry = self.find_in_directory(current_cluster, last)?;
+        if entry.attributes & ATTR_DIRECTORY != 0 {
+            return Err(FileSystemError::InvalidPath);
         }
+        Ok(entry)
+    }
+    
+    // Parse short filename (8.3 format). The bytes are in code page 437,
+    // with 05h standing for a first E5h, which marks a deleted entry.
+    // Windows keeps names whose base or extension is all lower case as
+    // short names, with a flag for each in nt_reserved.
+    fn parse_short_name(entry: &Fat32DirEntry) -> String {
+        let name = entry.name;
+        let part = |bytes: &[u8], lower: bool| -> String {
+            let length = bytes.iter().position(|&byte| byte == 0x20 || byte == 0).unwrap_or(bytes.len());
+            bytes[..length].iter().map(|&byte| {
+                let c = unicode::from_cp437(byte);
+                if lower { c.to_ascii_lowercase() } else { c }
+            }).collect()
+        };
         
+        let mut base = name;
+        if base[0] == 0x05 {
+            base[0] = 0xE5;
+        }
+        let mut result = part(&base[..8], entry.nt_reserved & NT_LOWER_BASE != 0);
+        let extension = part(&name[8..], entry.nt_reserved & NT_LOWER_EXTENSION != 0);
+        if !extension.is_empty() {
+            result.push('.');
+            result.push_str(&extension);
+        }
         result
     }
     
-    // List directory entries in a cluster
-    fn list_dir_cluster(&self, cluster: u32) -> Result<Vec<FileInfo>, FileSystemError> {
-        let mut files = Vec::new();
+    // The checksum of a short name that its long name entries hold
+    fn short_name_checksum(name: &[u8; 11]) -> u8 {
+        name.iter().fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte))
+    }
+    
+    // The entries of a directory, each with its name: the long name
+    // where one goes with it, else the short name
+    fn dir_entries(&self, cluster: u32) -> Result<Vec<(String, Fat32DirEntry)>, FileSystemError> {
         let data = self.read_cluster_chain(cluster)?;
+        let mut entries = Vec::new();
+        // The UTF-16 units of the long name being put together, with the
+        // checksum its entries carry
+        let mut long_name: Option<(Vec<u16>, u8)> = None;
         
-        let entries_per_cluster = (self.sectors_per_cluster as usize * SECTOR_SIZE) / BYTES_PER_DIR_ENTRY;
-        
-        for i in 0..entries_per_cluster {
-            let offset = i * BYTES_PER_DIR_ENTRY;
-            if offset + BYTES_PER_DIR_ENTRY > data.len() {
-                break;
-            }
-            
+        for raw in data.chunks_exact(BYTES_PER_DIR_ENTRY) {
             let entry = unsafe {
-                *(data[offset..].as_ptr() as *const Fat32DirEntry)
+                *(raw.as_ptr() as *const Fat32DirEntry)
             };
             
-            // Skip empty entries
             if entry.name[0] == 0x00 {
                 break;  // No more entries
             }
             if entry.name[0] == 0xE5 {
+                long_name = None;
                 continue;  // Deleted entry
             }
             
-            // Skip long name entries for now
-            if entry.attributes == ATTR_LONG_NAME {
+            // Long name entries come before their short entry, the last
+            // part first, each holding 13 units
+            if entry.attributes & 0x3F == ATTR_LONG_NAME {
+                let order = raw[0];
+                let checksum = raw[13];
+                let units = raw[1..11].chunks_exact(2)
+                    .chain(raw[14..26].chunks_exact(2))
+                    .chain(raw[28..32].chunks_exact(2))
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+                let units = units.take_while(|&unit| unit != 0).collect::<Vec<u16>>();
+                long_name = match long_name.take() {
+                    _ if order & LAST_LONG_ENTRY != 0 => Some((units, checksum)),
+                    Some((mut name, sum)) if sum == checksum => {
+                        let mut joined = units;
+                        joined.append(&mut name);
+                        Some((joined, sum))
+                    }
+                    _ => None,
+                };
                 continue;
             }
             
             // Skip volume label
             if entry.attributes & ATTR_VOLUME_ID != 0 {
+                long_name = None;
                 continue;
             }
             
-            let name = Self::parse_short_name(&entry.name);
-            let file_type = if entry.attributes & ATTR_DIRECTORY != 0 {
+            let name = match long_name.take() {
+                Some((units, sum)) if sum == Self::short_name_checksum(&entry.name) && !units.is_empty() => {
+                    unicode::decode_utf16(units)
+                }
+                _ => Self::parse_short_name(&entry),
+            };
+            entries.push((name, entry));
+        }
+        
+        Ok(entries)
+    }
+    
+    // List directory entries in a cluster
+    // Last write time in seconds since the epoch. FAT stores local time:
+    // the date as years since 1980, month and day, the time in two-second
+    // steps.
+    fn modified_time(entry: &Fat32DirEntry) -> u64 {
+        let (date, time) = (entry.write_date, entry.write_time);
+        let local = crate::time::DateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8,
+            millisecond: 0,
+        };
+        if !local.is_valid() {
+            return 0;
+        }
+        crate::time::zone::current().to_utc(local.to_unix()).max(0) as u64
+    }
+    
+    fn list_dir_cluster(&self, cluster: u32) -> Result<Vec<FileInfo>, FileSystemError> {
+        let entries = self.dir_entries(cluster)?;
+        Ok(entries.into_iter().map(|(name, entry)| Self::file_info(name, &entry)).collect())
+    }
+    
+    fn file_info(name: String, entry: &Fat32DirEntry) -> FileInfo {
+        FileInfo {
+            name,
+            size: entry.file_size as u64,
+            file_type: if entry.attributes & ATTR_DIRECTORY != 0 {
                 FileType::Directory
             } else {
                 FileType::Regular
-            };
-            
-            files.push(FileInfo {
-                name,
-                size: entry.file_size as u64,
-                file_type,
-                permissions: 0o755,
-            });
+            },
+            permissions: if entry.attributes & ATTR_READ_ONLY != 0 {
+                0o555
+            } else {
+                0o755
+            },
+            modified: Self::modified_time(entry),
         }
-        
-        Ok(files)
     }
     
-    // Find a file in a directory
+    // Find a file in a directory by its long or short name, without
+    // regard to case, and return it with its name as stored
+    fn lookup(&self, dir_cluster: u32, name: &str) -> Result<(String, Fat32DirEntry), FileSystemError> {
+        let upcase = UpCase::standard();
+        self.dir_entries(dir_cluster)?.into_iter()
+            .find(|(long, entry)| {
+                upcase.eq_ignore_case(long, name) || upcase.eq_ignore_case(&Self::parse_short_name(entry), name)
+            })
+            .ok_or(FileSystemError::NotFound)
+    }
+    
     fn find_in_directory(&self, dir_cluster: u32, name: &str) -> Result<Fat32DirEntry, FileSystemError> {
-        let data = self.read_cluster_chain(dir_cluster)?;
-        let name_upper = name.to_uppercase();
-        
-        let entries_per_cluster = (self.sectors_per_cluster as usize * SECTOR_SIZE) / BYTES_PER_DIR_ENTRY;
-        
-        for i in 0..entries_per_cluster {
-            let offset = i * BYTES_PER_DIR_ENTRY;
-            if offset + BYTES_PER_DIR_ENTRY > data.len() {
-                break;
-            }
-            
-            let entry = unsafe {
-                *(data[offset..].as_ptr() as *const Fat32DirEntry)
-            };
-            
-            if entry.name[0] == 0x00 {
-                break;
-            }
-            if entry.name[0] == 0xE5 || entry.attributes == ATTR_LONG_NAME {
-                continue;
-            }
-            
-            let entry_name = Self::parse_short_name(&entry.name).to_uppercase();
-            if entry_name == name_upper {
-                return Ok(entry);
-            }
-        }
-        
-        Err(FileSystemError::NotFound)
+        self.lookup(dir_cluster, name).map(|(_, entry)| entry)
     }
 }
 
 impl FileSystem for Fat32FileSystem {
+    fn fs_type(&self) -> &'static str {
+        "vfat"
+    }
+
     fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
         // Parse path and navigate to file
         let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
@@ -342,6 +465,32 @@ impl FileSystem for Fat32FileSystem {
         Err(FileSystemError::NotFound)
     }
     
+    // The volume is only read, so a file's chain stays good for as long as
+    // it is kept
+    fn read_at(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        let mut chain = match self.chains.remove(path) {
+            Some(chain) => chain,
+            None => {
+                let entry = self.find_file(path)?;
+                OpenChain {
+                    size: entry.file_size,
+                    first_cluster: (entry.first_cluster_high as u32) << 16 | entry.first_cluster_low as u32,
+                    clusters: Vec::new(),
+                }
+            }
+        };
+        let result = self.read_chain(&mut chain, offset, buffer);
+        if self.chains.len() >= OPEN_CHAINS {
+            self.chains.pop_first();
+        }
+        self.chains.insert(String::from(path), chain);
+        result
+    }
+    
+    fn file_size(&mut self, path: &str) -> Result<u64, FileSystemError> {
+        Ok(self.get_file_info(path)?.size)
+    }
+    
     fn write_file(&mut self, _path: &str, _data: &[u8]) -> Result<(), FileSystemError> {
         // Writing to FAT32 is complex - would need to:
         // 1. Find or create directory entry
@@ -390,6 +539,54 @@ impl FileSystem for Fat32FileSystem {
         Err(FileSystemError::IoError(String::from("Not implemented")))  // Not implemented yet
     }
     
+    fn block_map(&self, path: &str) -> Result<(usize, Vec<(u64, u64)>), FileSystemError> {
+        let entry = self.find_file(path)?;
+        
+        // Runs of consecutive clusters, cut off at the file's size
+        let mut remaining = (entry.file_size as u64).div_ceil(SECTOR_SIZE as u64);
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        let mut cluster = (entry.first_cluster_high as u32) << 16 | entry.first_cluster_low as u32;
+        while remaining > 0 && cluster >= 2 && cluster < END_OF_CLUSTER_CHAIN && cluster != BAD_CLUSTER {
+            let sector = self.cluster_to_sector(cluster) as u64;
+            let count = remaining.min(self.sectors_per_cluster as u64);
+            match extents.last_mut() {
+                Some((first, length)) if *first + *length == sector => *length += count,
+                _ => extents.push((sector, count)),
+            }
+            remaining -= count;
+            cluster = self.get_next_cluster(cluster)?;
+        }
+        if remaining > 0 {
+            return Err(FileSystemError::IoError(String::from("Cluster chain shorter than the file")));
+        }
+        Ok((self.disk_index, extents))
+    }
+    
+    // The FSInfo sector's free count is only a hint, so the FAT is counted
+    fn statfs(&self) -> Result<FsStats, FileSystemError> {
+        let clusters = self.boot_sector.total_sectors_32.saturating_sub(self.data_start_sector) / self.sectors_per_cluster;
+        // Entries 0 and 1 are reserved; the first cluster is 2
+        let entries = clusters as usize + 2;
+        let fat_sectors = (entries * FAT_ENTRY_SIZE as usize).div_ceil(SECTOR_SIZE);
+        let mut free = 0;
+        let mut data = alloc::vec![0u8; FAT_SECTORS_PER_READ as usize * SECTOR_SIZE];
+        for first in (0..fat_sectors as u32).step_by(FAT_SECTORS_PER_READ as usize) {
+            let count = FAT_SECTORS_PER_READ.min(fat_sectors as u32 - first);
+            let data = &mut data[..count as usize * SECTOR_SIZE];
+            block::read(self.disk_index, (self.fat_start_sector + first) as u64, count, data)
+                .map_err(|_| FileSystemError::IoError(String::from("Read error")))?;
+            let base = first as usize * SECTOR_SIZE / FAT_ENTRY_SIZE as usize;
+            free += data
+                .chunks_exact(FAT_ENTRY_SIZE as usize)
+                .enumerate()
+                .filter(|(index, _)| (2..entries).contains(&(base + index)))
+                .filter(|(_, entry)| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0x0FFFFFFF == 0)
+                .count() as u64;
+        }
+        let cluster_size = self.sectors_per_cluster as u64 * SECTOR_SIZE as u64;
+        Ok(FsStats { block_size: cluster_size, blocks: clusters as u64, free_blocks: free })
+    }
+    
     fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError> {
         let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
         
@@ -399,30 +596,18 @@ impl FileSystem for Fat32FileSystem {
                 size: 0,
                 file_type: FileType::Directory,
                 permissions: 0o755,
+                modified: 0,
             });
         }
         
         let mut current_cluster = self.root_dir_cluster;
         
         for (i, part) in parts.iter().enumerate() {
-            let entry = self.find_in_directory(current_cluster, part)?;
+            let (name, entry) = self.lookup(current_cluster, part)?;
             
             if i == parts.len() - 1 {
                 // This is the target
-                return Ok(FileInfo {
-                    name: Self::parse_short_name(&entry.name),
-                    size: entry.file_size as u64,
-                    file_type: if entry.attributes & ATTR_DIRECTORY != 0 {
-                        FileType::Directory
-                    } else {
-                        FileType::Regular
-                    },
-                    permissions: if entry.attributes & ATTR_READ_ONLY != 0 {
-                        0o555
-                    } else {
-                        0o755
-                    },
-                });
+                return Ok(Self::file_info(name, &entry));
             }
             
             if entry.attributes & ATTR_DIRECTORY == 0 {
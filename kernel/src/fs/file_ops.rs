@@ -1,9 +1,19 @@
 // File operations - handles file I/O for processes
-use alloc::{vec::Vec, string::String, collections::BTreeMap, boxed::Box};
+//
+// Reads and writes go through the page cache, so they see what shared
+// mappings of a file have stored and the other way round.
+//
+// Each process has its own descriptors. A descriptor refers to an open
+// file, which fork shares between parent and child along with its
+// position and byte-range locks; exec and spawn leave behind descriptors
+// marked close-on-exec.
+use alloc::{vec, vec::Vec, string::String, collections::BTreeMap, sync::Arc};
 use spin::Mutex;
 use lazy_static::lazy_static;
-use super::{FileSystemError, FileInfo, FileType};
-use crate::process::pcb::FileDescriptor;
+use super::{FileSystemError, FileInfo, FileType, FsControl, FsControlReply};
+use super::locks;
+use super::notify::{self, CHANGE_LAST_WRITE, CHANGE_SIZE};
+use crate::memory::page_cache::{self, FileKey};
 
 // File access modes
 bitflags::bitflags! {
@@ -15,6 +25,8 @@ bitflags::bitflags! {
         const CREATE = 0x8;
         const TRUNCATE = 0x10;
         const EXCLUSIVE = 0x20;
+        // Of the descriptor rather than the file: exec closes it
+        const CLOEXEC = 0x40;
     }
 }
 
@@ -26,29 +38,40 @@ pub enum SeekFrom {
     Current(i64),
 }
 
-// Open file handle
+// Open file
 #[derive(Debug)]
 pub struct FileHandle {
-    pub fd: i32,
+    // Global path, through the opener's mount namespace
     pub path: String,
     pub mode: FileMode,
     pub position: u64,
     pub inode: u64,
     pub size: u64,
+    // Who the file's byte-range locks belong to
+    lock_owner: u64,
 }
 
 impl FileHandle {
-    pub fn new(fd: i32, path: String, mode: FileMode, inode: u64, size: u64) -> Self {
+    pub fn new(path: String, mode: FileMode, inode: u64, size: u64) -> Self {
         Self {
-            fd,
             path,
             mode,
             position: 0,
             inode,
             size,
+            lock_owner: locks::new_owner(),
         }
     }
     
+    // Whether another open file's lock stops this I/O
+    fn check_locks(&self, offset: u64, length: usize, write: bool) -> Result<(), FileSystemError> {
+        locks::check(&self.path, Some(self.lock_owner), offset, length as u64, write)
+    }
+    
+    fn written(&self) {
+        notify::changed(&self.path, CHANGE_LAST_WRITE | CHANGE_SIZE);
+    }
+    
     pub fn can_read(&self) -> bool {
         self.mode.contains(FileMode::READ)
     }
@@ -56,123 +79,188 @@ impl FileHandle {
     pub fn can_write(&self) -> bool {
         self.mode.contains(FileMode::WRITE) || self.mode.contains(FileMode::APPEND)
     }
-}
-
-// File table - tracks all open files system-wide
-pub struct FileTable {
-    handles: BTreeMap<i32, FileHandle>,
-    next_fd: i32,
-}
-
-impl FileTable {
-    pub fn new() -> Self {
-        Self {
-            handles: BTreeMap::new(),
-            next_fd: 3,  // 0=stdin, 1=stdout, 2=stderr
-        }
-    }
     
-    pub fn open(&mut self, path: String, mode: FileMode) -> Result<i32, FileSystemError> {
-        // Check if file exists (would query VFS)
-        let inode = self.get_inode(&path)?;
-        let size = self.get_file_size(&path)?;
-        
-        // Allocate file descriptor
-        let fd = self.next_fd;
-        self.next_fd += 1;
-        
-        // Create file handle
-        let handle = FileHandle::new(fd, path, mode, inode, size);
-        self.handles.insert(fd, handle);
-        
-        Ok(fd)
-    }
-    
-    pub fn close(&mut self, fd: i32) -> Result<(), FileSystemError> {
-        self.handles.remove(&fd)
-            .ok_or(FileSystemError::NotFound)?;
-        Ok(())
+    pub fn key(&self) -> FileKey {
+        FileKey::Path(self.path.clone())
     }
     
-    pub fn read(&mut self, fd: i32, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
-        let handle = self.handles.get_mut(&fd)
-            .ok_or(FileSystemError::NotFound)?;
-        
-        if !handle.can_read() {
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        if !self.can_read() {
             return Err(FileSystemError::PermissionDenied);
         }
         
-        // Read from VFS
-        use super::vfs::VFS;
-        let vfs = VFS.lock();
-        let data = vfs.read_file(&handle.path)?;
-        
         // Read from current position
-        let start = handle.position as usize;
-        let available = data.len().saturating_sub(start);
-        let to_read = buffer.len().min(available);
-        
-        if to_read > 0 {
-            buffer[..to_read].copy_from_slice(&data[start..start + to_read]);
-            handle.position += to_read as u64;
-        }
+        self.check_locks(self.position, buffer.len(), false)?;
+        let to_read = page_cache::read(&self.key(), self.position, buffer)?;
+        self.position += to_read as u64;
         
         Ok(to_read)
     }
     
-    pub fn write(&mut self, fd: i32, data: &[u8]) -> Result<usize, FileSystemError> {
-        let handle = self.handles.get_mut(&fd)
-            .ok_or(FileSystemError::NotFound)?;
-        
-        if !handle.can_write() {
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FileSystemError> {
+        if !self.can_write() {
             return Err(FileSystemError::PermissionDenied);
         }
         
-        // Write to VFS
-        use super::vfs::VFS;
-        let mut vfs = VFS.lock();
-        
-        if handle.mode.contains(FileMode::APPEND) {
-            handle.position = handle.size;
+        let key = self.key();
+        if self.mode.contains(FileMode::APPEND) {
+            self.position = page_cache::size(&key).unwrap_or(self.size);
         }
         
-        // This would write at position
-        vfs.write_file(&handle.path, data)?;
+        // Into the page cache, written back later
+        self.check_locks(self.position, data.len(), true)?;
+        page_cache::write(&key, self.position, data)?;
+        self.written();
         
-        handle.position += data.len() as u64;
-        handle.size = handle.size.max(handle.position);
+        self.position += data.len() as u64;
+        self.size = self.size.max(self.position);
         
         Ok(data.len())
     }
     
-    pub fn seek(&mut self, fd: i32, pos: SeekFrom) -> Result<u64, FileSystemError> {
-        let handle = self.handles.get_mut(&fd)
-            .ok_or(FileSystemError::NotFound)?;
+    /// Read at `offset`, leaving the position where it is
+    pub fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize, FileSystemError> {
+        if !self.can_read() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        self.check_locks(offset, buffer.len(), false)?;
+        page_cache::read(&self.key(), offset, buffer)
+    }
+    
+    /// Write at `offset`, leaving the position where it is
+    pub fn write_at(&mut self, data: &[u8], offset: u64) -> Result<usize, FileSystemError> {
+        if !self.can_write() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        self.check_locks(offset, data.len(), true)?;
+        page_cache::write(&self.key(), offset, data)?;
+        self.written();
+        self.size = self.size.max(offset + data.len() as u64);
+        Ok(data.len())
+    }
+    
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FileSystemError> {
+        // Others may have grown the file since it was opened
+        self.size = page_cache::size(&self.key()).unwrap_or(self.size);
         
         let new_pos = match pos {
             SeekFrom::Start(offset) => offset,
             SeekFrom::End(offset) => {
                 if offset < 0 {
-                    handle.size.saturating_sub((-offset) as u64)
+                    self.size.saturating_sub((-offset) as u64)
                 } else {
-                    handle.size + offset as u64
+                    self.size + offset as u64
                 }
             },
             SeekFrom::Current(offset) => {
                 if offset < 0 {
-                    handle.position.saturating_sub((-offset) as u64)
+                    self.position.saturating_sub((-offset) as u64)
                 } else {
-                    handle.position + offset as u64
+                    self.position + offset as u64
                 }
             },
         };
         
-        handle.position = new_pos.min(handle.size);
-        Ok(handle.position)
+        self.position = new_pos.min(self.size);
+        Ok(self.position)
+    }
+}
+
+impl Drop for FileHandle {
+    // The last descriptor on the file has closed
+    fn drop(&mut self) {
+        locks::release(self.lock_owner);
+    }
+}
+
+// A process's descriptor for an open file
+#[derive(Debug, Clone)]
+struct Descriptor {
+    file: Arc<Mutex<FileHandle>>,
+    cloexec: bool,
+}
+
+// File table - every process's file descriptors, by (process, fd)
+pub struct FileTable {
+    handles: BTreeMap<(u32, i32), Descriptor>,
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        Self {
+            handles: BTreeMap::new(),
+        }
     }
     
-    pub fn get_handle(&self, fd: i32) -> Option<&FileHandle> {
-        self.handles.get(&fd)
+    pub fn open(&mut self, pid: u32, path: String, mode: FileMode) -> Result<i32, FileSystemError> {
+        use super::vfs::VFS;
+        
+        let write = mode.intersects(FileMode::WRITE | FileMode::APPEND);
+        let mut vfs = VFS.lock();
+        let path = match vfs.open(&path, write) {
+            Err(FileSystemError::NotFound | FileSystemError::FileNotFound) if mode.contains(FileMode::CREATE) => {
+                vfs.write_file(&path, &[])?;
+                vfs.open(&path, write)?
+            }
+            result => result?,
+        };
+        drop(vfs);
+        
+        let inode = self.get_inode(&path)?;
+        let size = page_cache::open(&path)?;
+        
+        // Lowest descriptor the process has free
+        let fd = (FIRST_FD..).find(|&fd| !self.handles.contains_key(&(pid, fd))).unwrap_or(FIRST_FD);
+        
+        let handle = FileHandle::new(path, mode - FileMode::CLOEXEC, inode, size);
+        self.handles.insert((pid, fd), Descriptor {
+            file: Arc::new(Mutex::new(handle)),
+            cloexec: mode.contains(FileMode::CLOEXEC),
+        });
+        
+        Ok(fd)
+    }
+    
+    pub fn close(&mut self, pid: u32, fd: i32) -> Result<(), FileSystemError> {
+        self.handles.remove(&(pid, fd))
+            .ok_or(FileSystemError::NotFound)?;
+        Ok(())
+    }
+    
+    fn descriptors(&self, pid: u32) -> impl Iterator<Item = (i32, &Descriptor)> {
+        self.handles.range((pid, i32::MIN)..=(pid, i32::MAX)).map(|(&(_, fd), descriptor)| (fd, descriptor))
+    }
+    
+    // Close every file an exiting process has open
+    pub fn release_process(&mut self, pid: u32) {
+        self.handles.retain(|&(owner, _), _| owner != pid);
+    }
+    
+    /// Give `child` the same descriptors as `parent`, on the same open
+    /// files; with `exec`, only those that stay open across exec
+    pub fn inherit(&mut self, parent: u32, child: u32, exec: bool) {
+        let inherited: Vec<(i32, Descriptor)> = self.descriptors(parent)
+            .filter(|(_, descriptor)| !(exec && descriptor.cloexec))
+            .map(|(fd, descriptor)| (fd, descriptor.clone()))
+            .collect();
+        for (fd, descriptor) in inherited {
+            self.handles.insert((child, fd), descriptor);
+        }
+    }
+    
+    /// Close the descriptors marked close-on-exec
+    pub fn exec(&mut self, pid: u32) {
+        self.handles.retain(|&(owner, _), descriptor| owner != pid || !descriptor.cloexec);
+    }
+    
+    pub fn set_cloexec(&mut self, pid: u32, fd: i32, cloexec: bool) -> Result<(), FileSystemError> {
+        let descriptor = self.handles.get_mut(&(pid, fd)).ok_or(FileSystemError::NotFound)?;
+        descriptor.cloexec = cloexec;
+        Ok(())
+    }
+    
+    pub fn get_handle(&self, pid: u32, fd: i32) -> Option<Arc<Mutex<FileHandle>>> {
+        self.handles.get(&(pid, fd)).map(|descriptor| descriptor.file.clone())
     }
     
     fn get_inode(&self, _path: &str) -> Result<u64, FileSystemError> {
@@ -180,10 +268,6 @@ impl FileTable {
         Ok(1)  // Dummy inode
     }
     
-    fn get_file_size(&self, _path: &str) -> Result<u64, FileSystemError> {
-        // This would query the VFS for file size
-        Ok(0)  // Dummy size
-    }
 }
 
 lazy_static! {
@@ -195,14 +279,83 @@ pub const STDIN_FD: i32 = 0;
 pub const STDOUT_FD: i32 = 1;
 pub const STDERR_FD: i32 = 2;
 
+// /dev/kvm hands out 3 to 258, WDM devices 320 up, io_uring rings 384 up,
+// the serial ports 512 up and TCP streams 768 up, so files are numbered
+// clear of them all
+const FIRST_FD: i32 = 1024;
+
+// One of the caller's open files; the table is not held while it is used
+fn file(fd: i32) -> Result<Arc<Mutex<FileHandle>>, FileSystemError> {
+    FILE_TABLE.lock().get_handle(current_pid(), fd).ok_or(FileSystemError::NotFound)
+}
+
+/// Whether `fd` is a file the caller has open
+pub fn owns(fd: usize) -> bool {
+    file(fd as i32).is_ok()
+}
+
+/// The page cache object behind one of the caller's files, and whether it
+/// was opened for reading and for writing
+pub fn mappable(fd: usize) -> Option<(FileKey, bool, bool)> {
+    let file = file(fd as i32).ok()?;
+    let handle = file.lock();
+    Some((handle.key(), handle.can_read(), handle.can_write()))
+}
+
+/// A file system control request on one of the caller's files; one that
+/// changes the file needs it open for writing. The file's cached pages are
+/// written back first, so the file system works on what was last written.
+pub fn control(fd: usize, request: FsControl) -> Result<FsControlReply, FileSystemError> {
+    let file = file(fd as i32)?;
+    let handle = file.lock();
+    let changes = !matches!(request, FsControl::GetCompression | FsControl::QueryAllocatedRanges { .. });
+    if changes && !handle.can_write() {
+        return Err(FileSystemError::PermissionDenied);
+    }
+
+    let key = handle.key();
+    if let FsControl::SetZeroData { offset, end } = request {
+        // Zeroed in the cache as well, so it does not hand back the old bytes
+        let end = end.min(page_cache::size(&key).unwrap_or(0));
+        if offset < end {
+            page_cache::write(&key, offset, &vec![0u8; (end - offset) as usize])?;
+        }
+    }
+    page_cache::write_back(&key)?;
+    super::vfs::VFS.lock().fs_control_unchecked(&handle.path, request)
+}
+
+pub fn release_process(pid: u32) {
+    FILE_TABLE.lock().release_process(pid);
+}
+
+/// Copy a forking process's descriptors to its child
+pub fn fork_process(parent: u32, child: u32) {
+    FILE_TABLE.lock().inherit(parent, child, false);
+}
+
+/// Give a spawned process the descriptors its parent keeps across exec
+pub fn spawn_process(parent: u32, child: u32) {
+    FILE_TABLE.lock().inherit(parent, child, true);
+}
+
+/// Close what a process has open close-on-exec, as it execs
+pub fn exec_process(pid: u32) {
+    FILE_TABLE.lock().exec(pid);
+}
+
+pub fn set_cloexec(fd: i32, cloexec: bool) -> Result<(), FileSystemError> {
+    FILE_TABLE.lock().set_cloexec(current_pid(), fd, cloexec)
+}
+
 // File system calls for processes
 pub fn sys_open(path: &str, flags: u32) -> Result<i32, FileSystemError> {
     let mode = FileMode::from_bits_truncate(flags);
-    FILE_TABLE.lock().open(String::from(path), mode)
+    FILE_TABLE.lock().open(current_pid(), String::from(path), mode)
 }
 
 pub fn sys_close(fd: i32) -> Result<(), FileSystemError> {
-    FILE_TABLE.lock().close(fd)
+    FILE_TABLE.lock().close(current_pid(), fd)
 }
 
 pub fn sys_read(fd: i32, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
@@ -212,7 +365,13 @@ pub fn sys_read(fd: i32, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
             // Read from keyboard buffer
             Ok(0)  // No input available
         },
-        _ => FILE_TABLE.lock().read(fd, buffer),
+        _ => {
+            let pid = current_pid();
+            crate::container::group::throttle_io(pid);
+            let read = file(fd)?.lock().read(buffer)?;
+            crate::container::group::charge_io(pid, read as u64);
+            Ok(read)
+        }
     }
 }
 
@@ -226,10 +385,63 @@ pub fn sys_write(fd: i32, data: &[u8]) -> Result<usize, FileSystemError> {
             }
             Ok(data.len())
         },
-        _ => FILE_TABLE.lock().write(fd, data),
+        _ => {
+            let pid = current_pid();
+            crate::container::group::throttle_io(pid);
+            let written = file(fd)?.lock().write(data)?;
+            crate::container::group::charge_io(pid, written as u64);
+            Ok(written)
+        }
     }
 }
 
+pub fn sys_pread(fd: i32, buffer: &mut [u8], offset: u64) -> Result<usize, FileSystemError> {
+    let pid = current_pid();
+    crate::container::group::throttle_io(pid);
+    let read = file(fd)?.lock().read_at(buffer, offset)?;
+    crate::container::group::charge_io(pid, read as u64);
+    Ok(read)
+}
+
+pub fn sys_pwrite(fd: i32, data: &[u8], offset: u64) -> Result<usize, FileSystemError> {
+    let pid = current_pid();
+    crate::container::group::throttle_io(pid);
+    let written = file(fd)?.lock().write_at(data, offset)?;
+    crate::container::group::charge_io(pid, written as u64);
+    Ok(written)
+}
+
+/// Write what the page cache holds of a file back to its file system
+pub fn sys_fsync(fd: i32) -> Result<(), FileSystemError> {
+    let key = file(fd)?.lock().key();
+    page_cache::write_back(&key)?;
+    Ok(())
+}
+
+/// Lock `length` bytes from `offset` of one of the caller's files, shared
+/// or `exclusive`; see `locks`. With `wait`, a conflicting lock is waited
+/// out rather than refused.
+pub fn sys_lock(fd: i32, offset: u64, length: u64, exclusive: bool, wait: bool) -> Result<(), FileSystemError> {
+    // The file is not held while waiting, so others may use it
+    let (path, owner) = {
+        let file = file(fd)?;
+        let handle = file.lock();
+        (handle.path.clone(), handle.lock_owner)
+    };
+    locks::lock(&path, owner, offset, length, exclusive, wait)
+}
+
+pub fn sys_unlock(fd: i32, offset: u64, length: u64) -> Result<(), FileSystemError> {
+    let file = file(fd)?;
+    let handle = file.lock();
+    locks::unlock(&handle.path, handle.lock_owner, offset, length)
+}
+
+// Caller whose resource group file transfers are charged to
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
 pub fn sys_seek(fd: i32, offset: i64, whence: i32) -> Result<u64, FileSystemError> {
     let pos = match whence {
         0 => SeekFrom::Start(offset as u64),
@@ -238,7 +450,7 @@ pub fn sys_seek(fd: i32, offset: i64, whence: i32) -> Result<u64, FileSystemErro
         _ => return Err(FileSystemError::InvalidPath),
     };
     
-    FILE_TABLE.lock().seek(fd, pos)
+    file(fd)?.lock().seek(pos)
 }
 
 // Directory operations
@@ -253,7 +465,7 @@ pub fn sys_mkdir(path: &str, _mode: u32) -> Result<(), FileSystemError> {
 
 pub fn sys_readdir(path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
     use super::vfs::VFS;
-    let vfs = VFS.lock();
+    let mut vfs = VFS.lock();
     vfs.list_directory(path)
 }
 
@@ -268,5 +480,6 @@ pub fn sys_stat(path: &str) -> Result<FileInfo, FileSystemError> {
         size: 0,
         file_type: FileType::Regular,
         permissions: 0o644,
+        modified: 0,
     })
 }
\ No newline at end of file
@@ -0,0 +1,541 @@
+// Initial ramdisk: a cpio or tar archive passed by the bootloader as the
+// module "initrd", unpacked into a RamFs that serves as the root file system
+// until real storage is mounted
+use super::ramfs::RamFs;
+use super::FileSystemError;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::boot::cmdline::Param;
+use crate::serial_println;
+
+/// Program run from the initrd when the command line gives no `rdinit=`
+pub const DEFAULT_INIT: &str = "/init";
+
+static RDINIT: Param<&str> = Param::new("rdinit", DEFAULT_INIT, "Init program in the initrd");
+
+// cpio "newc" format, as written by `cpio -H newc`, with or without checksums
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+// File type bits of a cpio mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+// POSIX ustar, with pax and GNU long names
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_REGULAR: u8 = b'0';
+const TAR_REGULAR_OLD: u8 = 0;
+const TAR_HARD_LINK: u8 = b'1';
+const TAR_SYMLINK: u8 = b'2';
+const TAR_DIRECTORY: u8 = b'5';
+const TAR_PAX: u8 = b'x';
+const TAR_GNU_LONG_NAME: u8 = b'L';
+const TAR_GNU_LONG_LINK: u8 = b'K';
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Cpio,
+    Tar,
+}
+
+impl Format {
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        if data.starts_with(CPIO_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+            Some(Format::Cpio)
+        } else if data.len() >= TAR_BLOCK_SIZE && data[257..].starts_with(TAR_MAGIC) {
+            Some(Format::Tar)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Cpio => "cpio",
+            Format::Tar => "tar",
+        }
+    }
+}
+
+fn corrupt(what: &str) -> FileSystemError {
+    FileSystemError::IoError(String::from("initrd: ") + what)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// Field of a name or link, up to its NUL
+fn c_str(bytes: &[u8]) -> Result<&str, FileSystemError> {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..end]).map_err(|_| corrupt("name is not UTF-8"))
+}
+
+/// Unpack an archive into a new RamFs
+pub fn unpack(data: &[u8]) -> Result<RamFs, FileSystemError> {
+    if data.starts_with(GZIP_MAGIC) {
+        return Err(corrupt("compressed archives are not supported"));
+    }
+    let mut fs = RamFs::new();
+    match Format::detect(data) {
+        Some(Format::Cpio) => unpack_cpio(data, &mut fs)?,
+        Some(Format::Tar) => unpack_tar(data, &mut fs)?,
+        None => return Err(corrupt("not a cpio or tar archive")),
+    }
+    Ok(fs)
+}
+
+fn cpio_field(header: &[u8], index: usize) -> Result<u32, FileSystemError> {
+    let start = CPIO_MAGIC.len() + index * 8;
+    core::str::from_utf8(&header[start..start + 8])
+        .ok()
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| corrupt("bad cpio header"))
+}
+
+fn unpack_cpio(data: &[u8], fs: &mut RamFs) -> Result<(), FileSystemError> {
+    // Hard-linked files carry their data only on the last link; the links
+    // before it are remembered by inode
+    let mut links: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    let mut offset = 0;
+    loop {
+        let header = data.get(offset..offset + CPIO_HEADER_SIZE).ok_or_else(|| corrupt("truncated cpio archive"))?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(corrupt("bad cpio magic"));
+        }
+        let inode = cpio_field(header, 0)?;
+        let mode = cpio_field(header, 1)?;
+        let nlink = cpio_field(header, 4)?;
+        let mtime = cpio_field(header, 5)? as u64;
+        let file_size = cpio_field(header, 6)? as usize;
+        let name_size = cpio_field(header, 11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = data.get(name_start..name_start + name_size).ok_or_else(|| corrupt("truncated cpio name"))?;
+        let name = c_str(name)?;
+        let data_start = align4(name_start + name_size);
+        let contents = data.get(data_start..data_start + file_size).ok_or_else(|| corrupt("truncated cpio file"))?;
+        offset = align4(data_start + file_size);
+
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let permissions = mode & !S_IFMT;
+        match mode & S_IFMT {
+            S_IFDIR => fs.insert_directory(name, permissions, mtime)?,
+            S_IFREG if nlink > 1 && file_size == 0 => {
+                links.entry(inode).or_default().push(name.to_string());
+                fs.insert_file(name, Vec::new(), permissions, mtime)?;
+            }
+            S_IFREG => {
+                for link in links.remove(&inode).unwrap_or_default() {
+                    fs.insert_file(&link, contents.to_vec(), permissions, mtime)?;
+                }
+                fs.insert_file(name, contents.to_vec(), permissions, mtime)?;
+            }
+            S_IFLNK => fs.insert_symlink(name, c_str(contents)?, mtime)?,
+            // Device nodes, FIFOs and sockets have no place in a RamFs
+            _ => {}
+        }
+    }
+}
+
+fn tar_number(field: &[u8]) -> Result<u64, FileSystemError> {
+    let text = c_str(field)?.trim_matches(' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| corrupt("bad tar number"))
+}
+
+// Records of a pax extended header, "<length> <key>=<value>\n"
+fn pax_records(mut data: &[u8]) -> Result<Vec<(&str, &str)>, FileSystemError> {
+    let mut records = Vec::new();
+    while !data.is_empty() && data[0] != 0 {
+        let space = data.iter().position(|&byte| byte == b' ').ok_or_else(|| corrupt("bad pax record"))?;
+        let length = core::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|&length| length > space + 1 && length <= data.len())
+            .ok_or_else(|| corrupt("bad pax record"))?;
+        let record = core::str::from_utf8(&data[space + 1..length - 1]).map_err(|_| corrupt("bad pax record"))?;
+        if let Some(pair) = record.split_once('=') {
+            records.push(pair);
+        }
+        data = &data[length..];
+    }
+    Ok(records)
+}
+
+// The header's checksum is the sum of its bytes, the checksum field counted
+// as spaces; some old tars summed them as signed
+fn tar_checksum_ok(header: &[u8]) -> Result<bool, FileSystemError> {
+    let expected = tar_number(&header[148..156])?;
+    let field = 148..156;
+    let (unsigned, signed) = header.iter().enumerate().fold((0u64, 0i64), |(unsigned, signed), (index, &byte)| {
+        let byte = if field.contains(&index) { b' ' } else { byte };
+        (unsigned + byte as u64, signed + byte as i8 as i64)
+    });
+    Ok(expected == unsigned || expected as i64 == signed)
+}
+
+fn unpack_tar(data: &[u8], fs: &mut RamFs) -> Result<(), FileSystemError> {
+    let mut long_name: Option<String> = None;
+    let mut long_link: Option<String> = None;
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = &data[offset..data.len().min(offset + TAR_BLOCK_SIZE)];
+        // The archive ends with zero blocks
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        if header.len() < TAR_BLOCK_SIZE {
+            return Err(corrupt("truncated tar header"));
+        }
+        if !tar_checksum_ok(header)? {
+            return Err(corrupt("bad tar header checksum"));
+        }
+        let size = tar_number(&header[124..136])? as usize;
+        let contents_start = offset + TAR_BLOCK_SIZE;
+        let contents = data.get(contents_start..contents_start + size).ok_or_else(|| corrupt("truncated tar file"))?;
+        offset = contents_start + size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+
+        let kind = header[156];
+        match kind {
+            TAR_PAX => {
+                for (key, value) in pax_records(contents)? {
+                    match key {
+                        "path" => long_name = Some(value.to_string()),
+                        "linkpath" => long_link = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            TAR_GNU_LONG_NAME => {
+                long_name = Some(c_str(contents)?.to_string());
+                continue;
+            }
+            TAR_GNU_LONG_LINK => {
+                long_link = Some(c_str(contents)?.to_string());
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = match long_name.take() {
+            Some(name) => name,
+            None => {
+                let (name, prefix) = (c_str(&header[0..100])?, c_str(&header[345..500])?);
+                if header[257..].starts_with(TAR_MAGIC) && !prefix.is_empty() {
+                    String::from(prefix) + "/" + name
+                } else {
+                    name.to_string()
+                }
+            }
+        };
+        let link = match long_link.take() {
+            Some(link) => link,
+            None => c_str(&header[157..257])?.to_string(),
+        };
+        let permissions = tar_number(&header[100..108])? as u32 & 0o7777;
+        let mtime = tar_number(&header[136..148])?;
+
+        match kind {
+            TAR_REGULAR | TAR_REGULAR_OLD if name.ends_with('/') => fs.insert_directory(&name, permissions, mtime)?,
+            TAR_REGULAR | TAR_REGULAR_OLD => fs.insert_file(&name, contents.to_vec(), permissions, mtime)?,
+            TAR_HARD_LINK => {
+                use super::FileSystem;
+                let target = fs.read_file(&link)?;
+                fs.insert_file(&name, target, permissions, mtime)?;
+            }
+            TAR_SYMLINK => fs.insert_symlink(&name, &link, mtime)?,
+            TAR_DIRECTORY => fs.insert_directory(&name, permissions, mtime)?,
+            // Devices, FIFOs and global pax headers
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Unpack the bootloader's "initrd" module, if it passed one
+pub fn load() -> Option<RamFs> {
+    let module = crate::boot::info().and_then(|info| info.module("initrd"))?;
+    let data = module.data();
+    match unpack(data) {
+        Ok(fs) => {
+            let format = Format::detect(data).map_or("?", |format| format.name());
+            serial_println!("initrd: {} archive, {} entries, {} bytes", format, fs.entry_count(), fs.size());
+            Some(fs)
+        }
+        Err(e) => {
+            serial_println!("initrd: cannot unpack: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Path of the early userspace init program, within the initrd
+pub fn init_path() -> &'static str {
+    RDINIT.get()
+}
+
+/// Start the init program of the initrd mounted at `mount_point` as a user
+/// process
+pub fn run_init(mount_point: &str) -> Result<u32, &'static str> {
+    let path = format!("{}/{}", mount_point.trim_end_matches('/'), init_path().trim_start_matches('/'));
+    let binary = super::vfs::VFS.lock().read_file_unchecked(&path).map_err(|_| "init program not found")?;
+    let pid = crate::process::executor::EXECUTOR.lock().create_process(String::from("init"), &binary)?;
+    serial_println!("initrd: started {} as PID {}", path, pid);
+    Ok(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{FileSystem, FileType};
+    use alloc::vec;
+
+    // One cpio "newc" entry, padded as the format requires
+    fn cpio_entry(archive: &mut Vec<u8>, inode: u32, mode: u32, nlink: u32, name: &str, contents: &[u8]) {
+        let fields = [inode, mode, 0, 0, nlink, 1_700_000_000, contents.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(CPIO_MAGIC);
+        for field in fields {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(contents);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    fn cpio_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, 1, S_IFDIR | 0o755, 2, "bin", b"");
+        cpio_entry(&mut archive, 2, S_IFREG | 0o700, 1, "init", b"#!/bin/sh\n");
+        cpio_entry(&mut archive, 3, S_IFLNK | 0o777, 1, "bin/sh", b"busybox");
+        // Hard links: the data comes with the last of them
+        cpio_entry(&mut archive, 4, S_IFREG | 0o755, 2, "bin/busybox", b"");
+        cpio_entry(&mut archive, 4, S_IFREG | 0o755, 2, "bin/ash", b"ELF");
+        cpio_entry(&mut archive, 5, 0o020000 | 0o600, 1, "dev/console", b"");
+        cpio_entry(&mut archive, 0, 0, 1, CPIO_TRAILER, b"");
+        archive
+    }
+
+    // A ustar header block for an entry, with its checksum filled in
+    fn tar_header(name: &str, kind: u8, size: usize, link: &str) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[136..147].copy_from_slice(b"14524770400");
+        header[156] = kind;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        set_tar_checksum(&mut header);
+        header
+    }
+
+    fn set_tar_checksum(header: &mut [u8]) {
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&byte| byte as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    }
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, kind: u8, contents: &[u8], link: &str) {
+        archive.extend_from_slice(&tar_header(name, kind, contents.len(), link));
+        archive.extend_from_slice(contents);
+        archive.resize(archive.len().div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE, 0);
+    }
+
+    fn tar_archive() -> Vec<u8> {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "etc/", TAR_DIRECTORY, b"", "");
+        tar_entry(&mut archive, "etc/hostname", TAR_REGULAR, b"rustos\n", "");
+        tar_entry(&mut archive, "etc/hosts", TAR_HARD_LINK, b"", "etc/hostname");
+        tar_entry(&mut archive, "etc/localtime", TAR_SYMLINK, b"", "/usr/share/zoneinfo/UTC");
+        archive.extend_from_slice(&[0; 2 * TAR_BLOCK_SIZE]);
+        archive
+    }
+
+    fn is_corrupt(result: Result<RamFs, FileSystemError>) -> bool {
+        matches!(result, Err(FileSystemError::IoError(_)))
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(Format::detect(&cpio_archive()), Some(Format::Cpio));
+        assert_eq!(Format::detect(b"070702"), Some(Format::Cpio));
+        assert_eq!(Format::detect(&tar_archive()), Some(Format::Tar));
+        assert_eq!(Format::detect(b"ustar"), None);
+        assert_eq!(Format::detect(&[0; 1024]), None);
+        assert!(is_corrupt(unpack(&[0x1f, 0x8b, 8, 0])));
+        assert!(is_corrupt(unpack(b"")));
+    }
+
+    #[test]
+    fn test_unpack_cpio() {
+        let fs = unpack(&cpio_archive()).unwrap();
+        assert_eq!(fs.read_file("/init").unwrap(), b"#!/bin/sh\n");
+        let init = fs.get_file_info("/init").unwrap();
+        assert_eq!((init.permissions, init.modified), (0o700, 1_700_000_000));
+        assert!(matches!(fs.get_file_info("/bin/sh").unwrap().file_type, FileType::SymLink));
+        // Both links of busybox get its data, and the link to it resolves
+        assert_eq!(fs.read_file("/bin/busybox").unwrap(), b"ELF");
+        assert_eq!(fs.read_file("/bin/ash").unwrap(), b"ELF");
+        assert_eq!(fs.read_file("/bin/sh").unwrap(), b"ELF");
+        // Device nodes are left out
+        assert!(fs.get_file_info("/dev/console").is_err());
+    }
+
+    #[test]
+    fn test_cpio_names_stay_inside_the_root() {
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, 1, S_IFREG | 0o644, 1, "../../etc/passwd", b"root");
+        cpio_entry(&mut archive, 0, 0, 1, CPIO_TRAILER, b"");
+        let fs = unpack(&archive).unwrap();
+        assert_eq!(fs.read_file("/etc/passwd").unwrap(), b"root");
+    }
+
+    #[test]
+    fn test_truncated_cpio() {
+        let archive = cpio_archive();
+        // Any archive cut short, even in the trailer's padding, is refused
+        for length in 0..archive.len() {
+            assert!(is_corrupt(unpack(&archive[..length])), "accepted {} of {} bytes", length, archive.len());
+        }
+        // as is one with no trailer
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, 1, S_IFREG | 0o644, 1, "a", b"data");
+        assert!(is_corrupt(unpack(&archive)));
+    }
+
+    #[test]
+    fn test_malformed_cpio_headers() {
+        let mut archive = cpio_archive();
+        // A field that is not hexadecimal
+        archive[CPIO_MAGIC.len() + 8..CPIO_MAGIC.len() + 16].copy_from_slice(b"0000x1ed");
+        assert!(is_corrupt(unpack(&archive)));
+
+        // Bad magic on an entry after the first
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, 1, S_IFDIR | 0o755, 2, "bin", b"");
+        let second = archive.len();
+        cpio_entry(&mut archive, 0, 0, 1, CPIO_TRAILER, b"");
+        archive[second..second + 6].copy_from_slice(b"070707");
+        assert!(is_corrupt(unpack(&archive)));
+
+        // Sizes running past the end of the archive
+        for field in [6, 11] {
+            let mut archive = cpio_archive();
+            let start = CPIO_MAGIC.len() + field * 8;
+            archive[start..start + 8].copy_from_slice(b"ffffffff");
+            assert!(is_corrupt(unpack(&archive)));
+        }
+
+        // A name that is not UTF-8
+        let mut archive = Vec::new();
+        cpio_entry(&mut archive, 1, S_IFREG | 0o644, 1, "name", b"");
+        archive[CPIO_HEADER_SIZE] = 0xff;
+        cpio_entry(&mut archive, 0, 0, 1, CPIO_TRAILER, b"");
+        assert!(is_corrupt(unpack(&archive)));
+    }
+
+    #[test]
+    fn test_unpack_tar() {
+        let fs = unpack(&tar_archive()).unwrap();
+        assert_eq!(fs.read_file("/etc/hostname").unwrap(), b"rustos\n");
+        assert_eq!(fs.read_file("/etc/hosts").unwrap(), b"rustos\n");
+        let info = fs.get_file_info("/etc/hostname").unwrap();
+        assert_eq!((info.permissions, info.modified), (0o644, 0o14524770400));
+        assert!(matches!(fs.get_file_info("/etc/localtime").unwrap().file_type, FileType::SymLink));
+        assert!(matches!(fs.get_file_info("/etc").unwrap().file_type, FileType::Directory));
+    }
+
+    #[test]
+    fn test_tar_long_names() {
+        let long = "usr/share/doc/".to_string() + &"x".repeat(120);
+        let mut archive = Vec::new();
+        // GNU long name
+        tar_entry(&mut archive, "././@LongLink", TAR_GNU_LONG_NAME, format!("{}-gnu\0", long).as_bytes(), "");
+        tar_entry(&mut archive, "ignored", TAR_REGULAR, b"gnu", "");
+        // pax path record; its length counts itself
+        let record = format!(" path={}-pax\n", long);
+        let record = format!("{}{}", record.len() + 3, record);
+        tar_entry(&mut archive, "PaxHeaders/x", TAR_PAX, record.as_bytes(), "");
+        tar_entry(&mut archive, "ignored", TAR_REGULAR, b"pax", "");
+        // ustar prefix
+        let mut header = tar_header("file", TAR_REGULAR, 6, "");
+        header[345..348].copy_from_slice(b"opt");
+        set_tar_checksum(&mut header);
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(b"prefix");
+        archive.resize(archive.len().div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE, 0);
+
+        let fs = unpack(&archive).unwrap();
+        assert_eq!(fs.read_file(&format!("/{}-gnu", long)).unwrap(), b"gnu");
+        assert_eq!(fs.read_file(&format!("/{}-pax", long)).unwrap(), b"pax");
+        assert_eq!(fs.read_file("/opt/file").unwrap(), b"prefix");
+        assert!(fs.get_file_info("/ignored").is_err());
+    }
+
+    #[test]
+    fn test_truncated_tar() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "etc/hostname", TAR_REGULAR, &[b'x'; 700], "");
+        // Cut in the header or in the contents
+        for length in [100, TAR_BLOCK_SIZE, TAR_BLOCK_SIZE + 699] {
+            assert!(is_corrupt(unpack(&archive[..length])), "accepted {} bytes", length);
+        }
+        // Cut at the end of a block, without the closing zero blocks: what is
+        // there is kept
+        let fs = unpack(&archive).unwrap();
+        assert_eq!(fs.read_file("/etc/hostname").unwrap().len(), 700);
+        // Trailing zeros short of a block end the archive too
+        archive.extend_from_slice(&[0; 100]);
+        assert!(unpack(&archive).is_ok());
+    }
+
+    #[test]
+    fn test_malformed_tar_headers() {
+        // A changed byte no longer matches the checksum
+        let mut archive = tar_archive();
+        archive[0] = b'E';
+        assert!(is_corrupt(unpack(&archive)));
+
+        // A size that is not octal
+        let mut header = tar_header("a", TAR_REGULAR, 0, "");
+        header[124..135].copy_from_slice(b"0000000009x");
+        set_tar_checksum(&mut header);
+        assert!(is_corrupt(unpack(&header)));
+
+        // A size running past the end of the archive
+        let mut header = tar_header("a", TAR_REGULAR, 0, "");
+        header[124..135].copy_from_slice(b"77777777777");
+        set_tar_checksum(&mut header);
+        assert!(is_corrupt(unpack(&header)));
+
+        // A hard link to nothing
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "a", TAR_HARD_LINK, b"", "missing");
+        assert!(unpack(&archive).is_err());
+
+        // pax records whose lengths do not add up
+        for record in ["99 path=a\n", "x path=a\n", "3 path=a\n", "nospace"] {
+            let mut archive = Vec::new();
+            tar_entry(&mut archive, "PaxHeaders/x", TAR_PAX, record.as_bytes(), "");
+            assert!(is_corrupt(unpack(&archive)), "accepted {:?}", record);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Byte-range locks
+//!
+//! An open file may lock ranges of its file, shared or exclusive, as
+//! LockFileEx does. Locks are mandatory, as on Windows: while one open file
+//! holds a range exclusively, reads and writes of it through any other
+//! fail, and while one holds it shared, writes through any other do. The
+//! holder's own I/O is never stopped by its locks.
+//!
+//! Locks belong to the open file, not to the descriptor or the process, so
+//! a child that inherits the file shares its locks; they go when the last
+//! descriptor on the file closes. Writes by path, which have no open file,
+//! are stopped by any lock.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::FileSystemError;
+
+// How long a waiting lock sleeps between tries
+const WAIT_STEP_NS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lock {
+    start: u64,
+    length: u64,
+    exclusive: bool,
+    owner: u64,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, length: u64) -> bool {
+        let end = start.saturating_add(length);
+        length > 0 && self.length > 0 && start < self.start.saturating_add(self.length) && self.start < end
+    }
+}
+
+// The locks on each file, by global path
+static LOCKS: Mutex<BTreeMap<String, Vec<Lock>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+/// A new owner, one per open file
+pub fn new_owner() -> u64 {
+    NEXT_OWNER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Lock `length` bytes of `path` from `start` for `owner`. A range another
+/// owner holds in a way that conflicts is waited for, or with `wait` false
+/// refused as `Locked`. An owner may take a shared lock over a range it
+/// holds already.
+pub fn lock(
+    path: &str,
+    owner: u64,
+    start: u64,
+    length: u64,
+    exclusive: bool,
+    wait: bool,
+) -> Result<(), FileSystemError> {
+    let lock = Lock { start, length, exclusive, owner };
+    loop {
+        {
+            let mut locks = LOCKS.lock();
+            let held = locks.entry(String::from(path)).or_default();
+            let conflicts = held.iter().any(|other| {
+                other.overlaps(start, length) && (exclusive || other.exclusive) && !(other.owner == owner && !exclusive)
+            });
+            if !conflicts {
+                held.push(lock);
+                return Ok(());
+            }
+        }
+        if !wait {
+            return Err(FileSystemError::Locked);
+        }
+        crate::time::hrtimer::sleep_ns(WAIT_STEP_NS);
+    }
+}
+
+/// Drop the lock `owner` holds on exactly `length` bytes from `start`, the
+/// last one taken if it holds several
+pub fn unlock(path: &str, owner: u64, start: u64, length: u64) -> Result<(), FileSystemError> {
+    let mut locks = LOCKS.lock();
+    let held = locks.get_mut(path).ok_or(FileSystemError::NotFound)?;
+    let index = held
+        .iter()
+        .rposition(|lock| lock.owner == owner && lock.start == start && lock.length == length)
+        .ok_or(FileSystemError::NotFound)?;
+    held.remove(index);
+    if held.is_empty() {
+        locks.remove(path);
+    }
+    Ok(())
+}
+
+/// Drop every lock of an open file as it closes
+pub fn release(owner: u64) {
+    let mut locks = LOCKS.lock();
+    locks.retain(|_, held| {
+        held.retain(|lock| lock.owner != owner);
+        !held.is_empty()
+    });
+}
+
+/// Whether `owner`, or None for a write by path, may read or write
+/// `length` bytes of `path` from `offset`
+pub fn check(path: &str, owner: Option<u64>, offset: u64, length: u64, write: bool) -> Result<(), FileSystemError> {
+    let locks = LOCKS.lock();
+    let Some(held) = locks.get(path) else {
+        return Ok(());
+    };
+    let blocked =
+        held.iter().any(|lock| Some(lock.owner) != owner && lock.overlaps(offset, length) && (write || lock.exclusive));
+    if blocked {
+        return Err(FileSystemError::Locked);
+    }
+    Ok(())
+}
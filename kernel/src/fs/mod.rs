@@ -1,11 +1,54 @@
 pub mod fat32;
+pub mod chkdsk;
 pub mod vfs;
 pub mod file_ops;
 pub mod ntfs;
 pub mod crypto;
+pub mod security;
+pub mod ramfs;
+pub mod initrd;
+pub mod procfs;
+pub mod ninep;
+pub mod unicode;
+pub mod locks;
+pub mod notify;
 
 use alloc::vec::Vec;
 use alloc::string::String;
+use alloc::format;
+use crate::boot::cmdline::{Param, Value};
+
+/// The root file system named by `root=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Root {
+    /// The initrd if there is one, otherwise the first disk
+    Auto,
+    Initrd,
+    /// The FAT32 volume on a disk, by index
+    Disk(usize),
+}
+
+impl Value for Root {
+    const KIND: &'static str = "initrd|diskN";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "auto" => Some(Root::Auto),
+            "initrd" => Some(Root::Initrd),
+            _ => text.strip_prefix("disk")?.parse().ok().map(Root::Disk),
+        }
+    }
+
+    fn show(&self) -> String {
+        match self {
+            Root::Auto => String::from("auto"),
+            Root::Initrd => String::from("initrd"),
+            Root::Disk(index) => format!("disk{}", index),
+        }
+    }
+}
+
+pub static ROOT: Param<Root> = Param::new("root", Root::Auto, "Root file system");
 
 #[derive(Debug, Clone)]
 pub struct File {
@@ -46,6 +89,67 @@ pub struct FileInfo {
     pub size: u64,
     pub file_type: FileType,
     pub permissions: u32,
+    pub modified: u64,  // Seconds since the Unix epoch (UTC), 0 if unknown
+}
+
+/// Size and use of a mounted file system, as `statfs` gives them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsStats {
+    pub block_size: u64,
+    /// Zero for file systems with no storage of their own, like /proc
+    pub blocks: u64,
+    pub free_blocks: u64,
+}
+
+/// A named data stream of a file, beside the unnamed one that is the
+/// file's own data
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Split a path naming an alternate data stream, `file:stream` or
+/// `file:stream:$DATA`, into the file and the stream's name. `file::$DATA`
+/// is the file's own data, as is a path with no stream.
+pub fn split_stream(path: &str) -> (&str, Option<&str>) {
+    let start = path.rfind(|c| c == '/' || c == '\\').map_or(0, |i| i + 1);
+    let colon = match path[start..].find(':') {
+        Some(i) if i > 0 => start + i,
+        _ => return (path, None),
+    };
+    let stream = &path[colon + 1..];
+    let stream = stream.strip_suffix(":$DATA").unwrap_or(stream);
+    (&path[..colon], if stream.is_empty() { None } else { Some(stream) })
+}
+
+/// Copy what `data` holds from `offset` into `buffer`; returns how many
+/// bytes that was
+pub fn copy_at(data: &[u8], offset: u64, buffer: &mut [u8]) -> usize {
+    let start = offset.min(data.len() as u64) as usize;
+    let count = (data.len() - start).min(buffer.len());
+    buffer[..count].copy_from_slice(&data[start..start + count]);
+    count
+}
+
+/// A request to a file system beyond reading and writing files, as
+/// Win32's DeviceIoControl makes with its FSCTL_ codes
+#[derive(Debug, Clone, Copy)]
+pub enum FsControl {
+    GetCompression,
+    SetCompression(bool),
+    SetSparse(bool),
+    /// Zero bytes from `offset` up to `end`, freeing clusters where it can
+    SetZeroData { offset: u64, end: u64 },
+    QueryAllocatedRanges { offset: u64, length: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub enum FsControlReply {
+    Done,
+    Compressed(bool),
+    /// The allocated parts of the range asked about, as (offset, length)
+    Ranges(Vec<(u64, u64)>),
 }
 
 #[derive(Debug)]
@@ -57,15 +161,75 @@ pub enum FileSystemError {
     IoError(String),
     NotSupported,
     FileNotFound,
+    /// Another open file holds a lock on the range; see `locks`
+    Locked,
 }
 
 pub trait FileSystem {
+    /// The type's name, as /proc/mounts gives it
+    fn fs_type(&self) -> &'static str;
+    
     fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError>;
     fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError>;
     fn create_directory(&mut self, path: &str) -> Result<(), FileSystemError>;
     fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError>;
     fn delete(&mut self, path: &str) -> Result<(), FileSystemError>;
     fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError>;
+    
+    /// Read `path` from `offset` into `buffer`; returns how many bytes were
+    /// read, fewer than asked only at the end of the file. By default the
+    /// whole file is read for it.
+    fn read_at(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        Ok(copy_at(&self.read_file(path)?, offset, buffer))
+    }
+    
+    /// The size of `path`'s data, by default read in full to measure it
+    fn file_size(&mut self, path: &str) -> Result<u64, FileSystemError> {
+        Ok(self.read_file(path)?.len() as u64)
+    }
+    
+    /// Self-relative security descriptor stored for `path`, if the file
+    /// system keeps one
+    fn get_security(&mut self, _path: &str) -> Result<Option<Vec<u8>>, FileSystemError> {
+        Ok(None)
+    }
+    
+    fn set_security(&mut self, _path: &str, _descriptor: &[u8]) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+    
+    /// The disk index and the runs of sectors, as (first, count), that hold
+    /// `path`'s data, for swap files read and written beneath the file system
+    fn block_map(&self, _path: &str) -> Result<(usize, Vec<(u64, u64)>), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+    
+    fn statfs(&self) -> Result<FsStats, FileSystemError> {
+        Ok(FsStats::default())
+    }
+    
+    fn fs_control(&mut self, _path: &str, _request: FsControl) -> Result<FsControlReply, FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+    
+    /// A file's named streams; none where the file system has no streams
+    fn list_streams(&mut self, _path: &str) -> Result<Vec<StreamInfo>, FileSystemError> {
+        Ok(Vec::new())
+    }
+    
+    fn read_named_stream(&mut self, _path: &str, _stream: &str) -> Result<Vec<u8>, FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+    
+    /// Replace a named stream of an existing file, creating the stream if
+    /// need be
+    fn write_named_stream(&mut self, _path: &str, _stream: &str, _data: &[u8]) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
+    
+    fn delete_named_stream(&mut self, _path: &str, _stream: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::NotSupported)
+    }
 }
 
 // Helper function for monitoring module
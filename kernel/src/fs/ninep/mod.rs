@@ -0,0 +1,409 @@
+//! 9P2000.L shared folders
+//!
+//! A client for the protocol QEMU's `-virtfs` and virtio-9p devices speak,
+//! so a directory on the host shows up in the guest without building a
+//! disk image. The same client runs over TCP to a 9P server such as diod.
+//!
+//! A share is mounted with `mount` or the `9p=` boot option, by its virtio
+//! mount tag or as `tcp:host[:port][/aname]`. Each file system call is a
+//! few requests answered before it returns: walk to the file, open it,
+//! read or write it in pieces no bigger than the negotiated message size,
+//! and clunk the fids.
+
+pub mod protocol;
+pub mod tcp;
+pub mod virtio;
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+use crate::fs::{FileInfo, FileSystem, FileSystemError, FileType, FsStats};
+use protocol::*;
+
+static MOUNTS: Param<&str> =
+    Param::new("9p", "", "9P shares to mount at boot, comma separated: tag:directory or tcp:host/aname:directory");
+
+// The message size asked for; the server may want less
+pub const MAX_MESSAGE: usize = 64 * 1024;
+const ROOT_FID: u32 = 0;
+
+/// A way to the server: one request out, its reply back
+pub trait Channel: Send {
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FileSystemError>;
+}
+
+fn io_error(text: &str) -> FileSystemError {
+    FileSystemError::IoError(String::from(text))
+}
+
+struct Session {
+    channel: Box<dyn Channel>,
+    msize: usize,
+    next_fid: u32,
+    free_fids: Vec<u32>,
+}
+
+impl Session {
+    // Send a request and check its reply is the one that answers it,
+    // returning the reply's body
+    fn rpc(&mut self, request: Message) -> Result<Vec<u8>, FileSystemError> {
+        let request = request.finish();
+        let reply = self.channel.rpc(&request)?;
+        if reply.len() < HEADER {
+            return Err(io_error("malformed 9P reply"));
+        }
+        match reply[4] {
+            RLERROR => Err(error(Reader::new(&reply[HEADER..]).u32()?)),
+            kind if kind == request[4] + 1 => Ok(reply[HEADER..].to_vec()),
+            _ => Err(io_error("unexpected 9P reply")),
+        }
+    }
+
+    fn request(kind: u8) -> Message {
+        // One request is outstanding at a time, so every one is tag 0
+        Message::new(kind, 0)
+    }
+
+    fn start(&mut self, aname: &str) -> Result<(), FileSystemError> {
+        let reply = self.rpc(Message::new(TVERSION, NOTAG).u32(MAX_MESSAGE as u32).string(VERSION))?;
+        let mut reader = Reader::new(&reply);
+        self.msize = (reader.u32()? as usize).min(MAX_MESSAGE);
+        if reader.string()? != VERSION || self.msize <= IO_HEADER {
+            return Err(FileSystemError::NotSupported);
+        }
+        // As root, with no authentication
+        self.rpc(Self::request(TATTACH).u32(ROOT_FID).u32(NOFID).string("root").string(aname).u32(0))?;
+        Ok(())
+    }
+
+    fn allocate_fid(&mut self) -> u32 {
+        self.free_fids.pop().unwrap_or_else(|| {
+            self.next_fid += 1;
+            self.next_fid
+        })
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        // The fid is gone even if the server complains
+        let _ = self.rpc(Self::request(TCLUNK).u32(fid));
+        self.free_fids.push(fid);
+    }
+
+    /// A new fid for `names` below `from`
+    fn walk(&mut self, from: u32, names: &[&str]) -> Result<u32, FileSystemError> {
+        let fid = self.allocate_fid();
+        let mut steps: Vec<&[&str]> = names.chunks(MAX_WALK).collect();
+        // A walk of no names clones the fid
+        if steps.is_empty() {
+            steps.push(&[]);
+        }
+        let mut current = from;
+        for step in steps {
+            let mut request = Self::request(TWALK).u32(current).u32(fid).u16(step.len() as u16);
+            for name in step {
+                request = request.string(name);
+            }
+            let failure = match self.rpc(request).and_then(|reply| Reader::new(&reply).u16()) {
+                Ok(walked) if walked as usize == step.len() => None,
+                Ok(_) => Some(FileSystemError::NotFound),
+                Err(e) => Some(e),
+            };
+            if let Some(e) = failure {
+                // A walk that falls short leaves the new fid as it was:
+                // unmade on the first step, and made on later ones
+                if current == from {
+                    self.free_fids.push(fid);
+                } else {
+                    self.clunk(fid);
+                }
+                return Err(e);
+            }
+            current = fid;
+        }
+        Ok(fid)
+    }
+
+    // Run `operation` on a fid for `names`, clunking it after
+    fn with_fid<T>(
+        &mut self,
+        names: &[&str],
+        operation: impl FnOnce(&mut Session, u32) -> Result<T, FileSystemError>,
+    ) -> Result<T, FileSystemError> {
+        let fid = self.walk(ROOT_FID, names)?;
+        let result = operation(self, fid);
+        self.clunk(fid);
+        result
+    }
+
+    // Run `operation` on a clone of `fid`, clunking it after
+    fn with_clone<T>(
+        &mut self,
+        fid: u32,
+        operation: impl FnOnce(&mut Session, u32) -> Result<T, FileSystemError>,
+    ) -> Result<T, FileSystemError> {
+        let clone = self.walk(fid, &[])?;
+        let result = operation(self, clone);
+        self.clunk(clone);
+        result
+    }
+
+    fn getattr(&mut self, fid: u32) -> Result<Attributes, FileSystemError> {
+        let reply = self.rpc(Self::request(TGETATTR).u32(fid).u64(GETATTR_BASIC))?;
+        Attributes::read(&mut Reader::new(&reply))
+    }
+
+    // Open `fid`, returning the most one read or write should move
+    fn open(&mut self, fid: u32, flags: u32) -> Result<usize, FileSystemError> {
+        let reply = self.rpc(Self::request(TLOPEN).u32(fid).u32(flags))?;
+        let mut reader = Reader::new(&reply);
+        reader.qid()?;
+        Ok(self.io_size(reader.u32()?))
+    }
+
+    // Create and open `name` in the directory `fid`, which becomes the file
+    fn create(&mut self, fid: u32, name: &str, mode: u32) -> Result<usize, FileSystemError> {
+        let request = Self::request(TLCREATE).u32(fid).string(name).u32(O_WRONLY | O_TRUNC).u32(mode).u32(0);
+        let reply = self.rpc(request)?;
+        let mut reader = Reader::new(&reply);
+        reader.qid()?;
+        Ok(self.io_size(reader.u32()?))
+    }
+
+    fn io_size(&self, iounit: u32) -> usize {
+        let most = self.msize - IO_HEADER;
+        if iounit == 0 { most } else { (iounit as usize).min(most) }
+    }
+
+    fn read_all(&mut self, fid: u32, chunk: usize) -> Result<Vec<u8>, FileSystemError> {
+        let mut data = Vec::new();
+        loop {
+            let reply = self.rpc(Self::request(TREAD).u32(fid).u64(data.len() as u64).u32(chunk as u32))?;
+            let bytes = Reader::new(&reply).data()?;
+            if bytes.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(bytes);
+        }
+    }
+
+    fn write_all(&mut self, fid: u32, chunk: usize, data: &[u8]) -> Result<(), FileSystemError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let piece = &data[offset..(offset + chunk).min(data.len())];
+            let reply = self.rpc(Self::request(TWRITE).u32(fid).u64(offset as u64).data(piece))?;
+            let written = Reader::new(&reply).u32()? as usize;
+            if written == 0 {
+                return Err(io_error("9P server wrote nothing"));
+            }
+            offset += written;
+        }
+        Ok(())
+    }
+
+    // The names in an open directory
+    fn read_directory(&mut self, fid: u32) -> Result<Vec<String>, FileSystemError> {
+        let mut names = Vec::new();
+        let mut offset = 0;
+        let chunk = (self.msize - IO_HEADER) as u32;
+        loop {
+            let reply = self.rpc(Self::request(TREADDIR).u32(fid).u64(offset).u32(chunk))?;
+            let mut entries = Reader::new(Reader::new(&reply).data()?);
+            if entries.is_empty() {
+                return Ok(names);
+            }
+            while !entries.is_empty() {
+                entries.qid()?;
+                offset = entries.u64()?;
+                entries.u8()?;
+                let name = entries.string()?;
+                if name != "." && name != ".." {
+                    names.push(name);
+                }
+            }
+        }
+    }
+}
+
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|component| !component.is_empty() && *component != ".").collect()
+}
+
+// A path's directory and last name
+fn split(path: &str) -> Result<(Vec<&str>, &str), FileSystemError> {
+    let mut names = components(path);
+    let name = names.pop().ok_or(FileSystemError::InvalidPath)?;
+    Ok((names, name))
+}
+
+fn file_info(name: &str, attributes: &Attributes) -> FileInfo {
+    let file_type = match attributes.file_type() {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::SymLink,
+        S_IFREG => FileType::Regular,
+        _ => FileType::Device,
+    };
+    FileInfo {
+        name: name.to_string(),
+        size: attributes.size,
+        file_type,
+        permissions: attributes.mode & 0o777,
+        modified: attributes.mtime,
+    }
+}
+
+/// A mounted share
+pub struct NinePFs {
+    session: Mutex<Session>,
+}
+
+impl NinePFs {
+    /// Start a session over `channel` and attach to `aname`, which the
+    /// server may ignore
+    pub fn new(channel: Box<dyn Channel>, aname: &str) -> Result<NinePFs, FileSystemError> {
+        let mut session = Session { channel, msize: MAX_MESSAGE, next_fid: ROOT_FID, free_fids: Vec::new() };
+        session.start(aname)?;
+        Ok(NinePFs { session: Mutex::new(session) })
+    }
+}
+
+impl Drop for NinePFs {
+    fn drop(&mut self) {
+        self.session.get_mut().clunk(ROOT_FID);
+    }
+}
+
+impl FileSystem for NinePFs {
+    fn fs_type(&self) -> &'static str {
+        "9p"
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        self.session.lock().with_fid(&components(path), |session, fid| {
+            let chunk = session.open(fid, O_RDONLY)?;
+            session.read_all(fid, chunk)
+        })
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
+        let (directory, name) = split(path)?;
+        let session = self.session.get_mut();
+        let parent = session.walk(ROOT_FID, &directory)?;
+        // An existing file is truncated; otherwise the directory's fid
+        // becomes the new file's
+        let (fid, opened) = match session.walk(parent, &[name]) {
+            Ok(fid) => {
+                session.clunk(parent);
+                (fid, session.open(fid, O_WRONLY | O_TRUNC))
+            }
+            Err(FileSystemError::NotFound) => (parent, session.create(parent, name, 0o644)),
+            Err(e) => {
+                session.clunk(parent);
+                return Err(e);
+            }
+        };
+        let result = opened.and_then(|chunk| session.write_all(fid, chunk, data));
+        session.clunk(fid);
+        result
+    }
+
+    fn create_directory(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (directory, name) = split(path)?;
+        self.session.get_mut().with_fid(&directory, |session, fid| {
+            session.rpc(Session::request(TMKDIR).u32(fid).string(name).u32(0o755).u32(0)).map(|_| ())
+        })
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        self.session.lock().with_fid(&components(path), |session, directory| {
+            // A fid that is open cannot be walked from, so a clone is read
+            let names = session.with_clone(directory, |session, fid| {
+                session.open(fid, O_RDONLY)?;
+                session.read_directory(fid)
+            })?;
+            let mut entries = Vec::new();
+            for name in names {
+                // Entries that vanish meanwhile are left out
+                let fid = match session.walk(directory, &[&name]) {
+                    Ok(fid) => fid,
+                    Err(_) => continue,
+                };
+                if let Ok(attributes) = session.getattr(fid) {
+                    entries.push(file_info(&name, &attributes));
+                }
+                session.clunk(fid);
+            }
+            Ok(entries)
+        })
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (directory, name) = split(path)?;
+        self.session.get_mut().with_fid(&directory, |session, parent| {
+            let fid = session.walk(parent, &[name])?;
+            let attributes = session.getattr(fid);
+            session.clunk(fid);
+            let flags = if attributes?.file_type() == S_IFDIR { AT_REMOVEDIR } else { 0 };
+            session.rpc(Session::request(TUNLINKAT).u32(parent).string(name).u32(flags)).map(|_| ())
+        })
+    }
+
+    fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError> {
+        let names = components(path);
+        let name = names.last().copied().unwrap_or("/");
+        self.session.lock().with_fid(&names, |session, fid| Ok(file_info(name, &session.getattr(fid)?)))
+    }
+
+    fn statfs(&self) -> Result<FsStats, FileSystemError> {
+        let reply = self.session.lock().rpc(Session::request(TSTATFS).u32(ROOT_FID))?;
+        let mut reader = Reader::new(&reply);
+        let _kind = reader.u32()?;
+        let block_size = reader.u32()? as u64;
+        let blocks = reader.u64()?;
+        let _free = reader.u64()?;
+        // What an unprivileged user may still use
+        let free_blocks = reader.u64()?;
+        Ok(FsStats { block_size, blocks, free_blocks })
+    }
+}
+
+/// Mount `source` on `directory`: a virtio mount tag, or
+/// `tcp:host[:port][/aname]`
+pub fn mount(source: &str, directory: &str) -> Result<(), FileSystemError> {
+    if crate::fs::vfs::VFS.lock().is_mounted(directory) {
+        return Err(FileSystemError::AlreadyExists);
+    }
+    // The server is talked to without the VFS held
+    let fs = match source.strip_prefix("tcp:") {
+        Some(address) => {
+            let (address, aname) = address.split_once('/').unwrap_or((address, ""));
+            NinePFs::new(Box::new(tcp::Connection::open(address)?), &alloc::format!("/{}", aname))?
+        }
+        None => NinePFs::new(Box::new(virtio::take(source)?), "")?,
+    };
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    if vfs.is_mounted(directory) {
+        return Err(FileSystemError::AlreadyExists);
+    }
+    vfs.mount(directory.to_string(), Box::new(fs));
+    Ok(())
+}
+
+/// Mount the shares the command line names
+pub fn init() {
+    for entry in MOUNTS.get().split(',').filter(|entry| !entry.is_empty()) {
+        let Some((source, directory)) = entry.rsplit_once(':') else {
+            crate::pr_warn!("9p: {}: expected source:directory", entry);
+            continue;
+        };
+        match mount(source, directory) {
+            Ok(()) => crate::pr_info!("9p: {} mounted on {}", source, directory),
+            Err(e) => crate::pr_warn!("9p: {}: {:?}", source, e),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// 9P2000.L messages
+//
+// Every message is size[4] type[1] tag[2] and a body; numbers are
+// little-endian and strings a two-byte length and UTF-8. Only the
+// messages the client sends, and their replies, are here.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::fs::FileSystemError;
+
+pub const VERSION: &str = "9P2000.L";
+pub const NOTAG: u16 = 0xFFFF;
+pub const NOFID: u32 = 0xFFFF_FFFF;
+// size, type and tag
+pub const HEADER: usize = 7;
+// What a Rread or Twrite adds to its data
+pub const IO_HEADER: usize = 24;
+// Names in one Twalk
+pub const MAX_WALK: usize = 16;
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TGETATTR: u8 = 24;
+pub const TREADDIR: u8 = 40;
+pub const TMKDIR: u8 = 72;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+// Linux open flags, as Tlopen and Tlcreate take them
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_TRUNC: u32 = 0o1000;
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+// Tgetattr's mask for the basic fields
+pub const GETATTR_BASIC: u64 = 0x7FF;
+
+// File types in a mode
+const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// A request being built
+pub struct Message {
+    bytes: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(kind: u8, tag: u16) -> Message {
+        let mut bytes = alloc::vec![0; 4];
+        bytes.push(kind);
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        Message { bytes }
+    }
+
+    pub fn u32(mut self, value: u32) -> Message {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Message {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn string(mut self, value: &str) -> Message {
+        self.bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        self.bytes.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    pub fn u16(mut self, value: u16) -> Message {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Message {
+        self.bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.bytes.extend_from_slice(data);
+        self
+    }
+
+    /// The message with its size filled in
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.bytes.len() as u32;
+        self.bytes[..4].copy_from_slice(&size.to_le_bytes());
+        self.bytes
+    }
+}
+
+fn malformed() -> FileSystemError {
+    FileSystemError::IoError(String::from("malformed 9P reply"))
+}
+
+/// A reply's body, read field by field
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, at: 0 }
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], FileSystemError> {
+        let bytes = self.bytes.get(self.at..self.at + length).ok_or_else(malformed)?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, FileSystemError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, FileSystemError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, FileSystemError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, FileSystemError> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    pub fn string(&mut self) -> Result<String, FileSystemError> {
+        let length = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    /// A count[4] and that many bytes
+    pub fn data(&mut self) -> Result<&'a [u8], FileSystemError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn qid(&mut self) -> Result<Qid, FileSystemError> {
+        Ok(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+}
+
+/// The server's identity for a file
+#[derive(Debug, Clone, Copy)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+/// The fields of an Rgetattr the file system uses
+#[derive(Debug, Clone, Copy)]
+pub struct Attributes {
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl Attributes {
+    pub fn read(reader: &mut Reader) -> Result<Attributes, FileSystemError> {
+        let _valid = reader.u64()?;
+        reader.qid()?;
+        let mode = reader.u32()?;
+        // uid, gid, nlink, rdev
+        reader.take(4 + 4 + 8 + 8)?;
+        let size = reader.u64()?;
+        // blksize, blocks, atime
+        reader.take(8 + 8 + 16)?;
+        let mtime = reader.u64()?;
+        Ok(Attributes { mode, size, mtime })
+    }
+
+    pub fn file_type(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+}
+
+/// What a Linux errno in an Rlerror means to the VFS
+pub fn error(errno: u32) -> FileSystemError {
+    match errno {
+        2 => FileSystemError::NotFound,
+        1 | 13 | 30 => FileSystemError::PermissionDenied,
+        17 => FileSystemError::AlreadyExists,
+        20 | 21 | 22 | 36 | 39 => FileSystemError::InvalidPath,
+        95 => FileSystemError::NotSupported,
+        errno => FileSystemError::IoError(alloc::format!("9P server error {}", errno)),
+    }
+}
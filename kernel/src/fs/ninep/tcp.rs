@@ -0,0 +1,95 @@
+// 9P over TCP
+//
+// The file system waits for each reply, so while it does this runs the
+// network itself: received frames are handed to the stack and its timers
+// run, as the main loop would. Messages are framed by their size field.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::fs::FileSystemError;
+use crate::net::tcp::{self, TcpState};
+use crate::time::clocksource::now_ns;
+
+use super::{Channel, MAX_MESSAGE};
+
+pub const DEFAULT_PORT: u16 = 564;
+const CONNECT_TIMEOUT_NS: u64 = 5_000_000_000;
+const REPLY_TIMEOUT_NS: u64 = 10_000_000_000;
+
+fn io_error(text: &str) -> FileSystemError {
+    FileSystemError::IoError(String::from(text))
+}
+
+// Let the network stack run once
+fn step() {
+    crate::net::interface::poll();
+    tcp::poll_timers();
+    core::hint::spin_loop();
+}
+
+pub struct Connection {
+    conn: u64,
+    input: Vec<u8>,
+}
+
+impl Connection {
+    /// Connect to `host[:port]`
+    pub fn open(address: &str) -> Result<Connection, FileSystemError> {
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| FileSystemError::InvalidPath)?),
+            None => (address, DEFAULT_PORT),
+        };
+        let remote = crate::net::dns::resolve_hostname(host).ok_or(FileSystemError::NotFound)?;
+        let conn = tcp::connect(crate::net::socket::ephemeral_port(), remote, port).map_err(io_error)?;
+        let connection = Connection { conn, input: Vec::new() };
+        let deadline = now_ns() + CONNECT_TIMEOUT_NS;
+        loop {
+            match tcp::state(conn) {
+                Some(TcpState::Established) => return Ok(connection),
+                Some(TcpState::SynSent) if now_ns() < deadline => step(),
+                _ => return Err(io_error("9P server did not answer")),
+            }
+        }
+    }
+}
+
+impl Channel for Connection {
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FileSystemError> {
+        let deadline = now_ns() + REPLY_TIMEOUT_NS;
+        let mut sent = 0;
+        loop {
+            if !matches!(tcp::state(self.conn), Some(TcpState::Established)) {
+                return Err(io_error("9P server closed the connection"));
+            }
+            if sent < request.len() {
+                let window = tcp::send_window(self.conn).min(request.len() - sent);
+                if window > 0 {
+                    tcp::send(self.conn, &request[sent..sent + window]).map_err(io_error)?;
+                    sent += window;
+                }
+            }
+            if let Ok(data) = tcp::recv(self.conn, MAX_MESSAGE) {
+                self.input.extend_from_slice(&data);
+            }
+            if self.input.len() >= 4 {
+                let size = u32::from_le_bytes([self.input[0], self.input[1], self.input[2], self.input[3]]) as usize;
+                if size > MAX_MESSAGE {
+                    return Err(io_error("9P reply too long"));
+                }
+                if self.input.len() >= size {
+                    return Ok(self.input.drain(..size).collect());
+                }
+            }
+            if now_ns() >= deadline {
+                return Err(io_error("9P server did not answer"));
+            }
+            step();
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = tcp::close(self.conn);
+    }
+}
@@ -0,0 +1,180 @@
+// 9P over virtio
+//
+// A virtio-9p device has one queue and a mount tag naming the share. Each
+// request goes down as two buffers, the message for the device to read and
+// room for its reply, and the driver waits for it to come back.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::fs::FileSystemError;
+use crate::memory::frame_allocator::FRAME_ALLOCATOR;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER};
+use crate::virtio::{self, queue::Buffer, Queue, Transport};
+
+use super::{Channel, MAX_MESSAGE};
+
+// The device has its mount tag in its configuration
+const F_MOUNT_TAG: u64 = 1;
+const QUEUE_SIZE: u16 = 16;
+// Writes to the host's disk can take a while
+const TIMEOUT_MS: u64 = 10_000;
+
+struct Share {
+    device: DeviceId,
+    tag: String,
+    // Taken while the share is mounted
+    channel: Option<VirtioChannel>,
+}
+
+static SHARES: Mutex<Vec<Share>> = Mutex::new(Vec::new());
+
+/// The mount tags of the virtio-9p devices, and whether each is mounted
+pub fn shares() -> Vec<(String, bool)> {
+    SHARES.lock().iter().map(|share| (share.tag.clone(), share.channel.is_none())).collect()
+}
+
+/// The channel to the device with mount tag `tag`, which is in use until
+/// the lease is dropped
+pub(super) fn take(tag: &str) -> Result<Lease, FileSystemError> {
+    let mut shares = SHARES.lock();
+    let share = shares.iter_mut().find(|share| share.tag == tag).ok_or(FileSystemError::NotFound)?;
+    let channel = share.channel.take().ok_or_else(|| FileSystemError::IoError(String::from("share already mounted")))?;
+    Ok(Lease { device: share.device, channel: Some(channel) })
+}
+
+struct VirtioChannel {
+    transport: Transport,
+    queue: Queue,
+    // Physical address of the request buffer, with the reply buffer after
+    // it, MAX_MESSAGE each
+    buffers: u64,
+}
+
+impl VirtioChannel {
+    fn buffer(&self, index: u64) -> &'static mut [u8] {
+        let address = PHYS_MEM_OFFSET + self.buffers + index * MAX_MESSAGE as u64;
+        unsafe { core::slice::from_raw_parts_mut(address as *mut u8, MAX_MESSAGE) }
+    }
+
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FileSystemError> {
+        if request.len() > MAX_MESSAGE {
+            return Err(FileSystemError::InvalidPath);
+        }
+        self.buffer(0)[..request.len()].copy_from_slice(request);
+        let buffers = [
+            Buffer { address: self.buffers, length: request.len() as u32, writable: false },
+            Buffer { address: self.buffers + MAX_MESSAGE as u64, length: MAX_MESSAGE as u32, writable: true },
+        ];
+        let to_error = |e: &str| FileSystemError::IoError(e.to_string());
+        let head = self.queue.submit(&buffers).map_err(to_error)?;
+        let written = self.queue.wait(head, TIMEOUT_MS).map_err(to_error)? as usize;
+        let reply = &self.buffer(1)[..written.min(MAX_MESSAGE)];
+        // The reply's own size is the one to go by
+        let size = reply.get(..4).map_or(0, |size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize);
+        reply.get(..size).map(<[u8]>::to_vec).ok_or_else(|| to_error("short 9P reply"))
+    }
+}
+
+impl Drop for VirtioChannel {
+    // The device lets go of the queue and buffers before they are freed
+    fn drop(&mut self) {
+        let _ = self.transport.reset();
+        let first = PhysFrame::containing_address(x86_64::PhysAddr::new(self.buffers));
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        for frame in PhysFrame::range(first, first + (2 * MAX_MESSAGE / 4096) as u64) {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// A mounted share's hold on its device
+pub struct Lease {
+    device: DeviceId,
+    channel: Option<VirtioChannel>,
+}
+
+impl Channel for Lease {
+    fn rpc(&mut self, request: &[u8]) -> Result<Vec<u8>, FileSystemError> {
+        match self.channel.as_mut() {
+            Some(channel) => channel.rpc(request),
+            None => Err(FileSystemError::IoError(String::from("device removed"))),
+        }
+    }
+}
+
+impl Drop for Lease {
+    // Hand the channel back to its share; if the device has gone the
+    // channel is dropped and stops it
+    fn drop(&mut self) {
+        let mut shares = SHARES.lock();
+        if let Some(share) = shares.iter_mut().find(|share| share.device == self.device) {
+            share.channel = self.channel.take();
+        }
+    }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+/// Binds virtio-9p devices
+pub static PCI_DRIVER: VirtioNinePDriver = VirtioNinePDriver;
+
+pub struct VirtioNinePDriver;
+
+impl Driver for VirtioNinePDriver {
+    fn name(&self) -> &'static str {
+        "virtio-9p"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::PciId { vendor: virtio::VENDOR, device: 0x1009 },
+            Match::PciId { vendor: virtio::VENDOR, device: 0x1040 + virtio::TYPE_9P },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        if virtio::device_type(&pci) != Some(virtio::TYPE_9P) {
+            return Err(ProbeError::NoDevice);
+        }
+        let transport = Transport::new(&pci).map_err(ProbeError::Failed)?;
+        if transport.negotiate(F_MOUNT_TAG).map_err(ProbeError::Failed)? & F_MOUNT_TAG == 0 {
+            return Err(ProbeError::Failed("no mount tag"));
+        }
+        let queue = transport.queue(0, QUEUE_SIZE).map_err(ProbeError::Failed)?;
+        transport.start();
+        let length = transport.config16(0) as u64;
+        let tag: Vec<u8> = (0..length).map(|i| transport.config8(2 + i)).collect();
+        let tag = String::from_utf8_lossy(&tag).into_owned();
+        let Some(buffers) = FRAME_ALLOCATOR.lock().allocate_contiguous(2 * MAX_MESSAGE / 4096, None) else {
+            let _ = transport.reset();
+            return Err(ProbeError::Failed("out of memory"));
+        };
+        let channel = VirtioChannel { transport, queue, buffers: buffers.start_address().as_u64() };
+        crate::serial_println!("virtio-9p: share \"{}\" on {}", tag, device.name);
+        SHARES.lock().push(Share { device: device.id, tag, channel: Some(channel) });
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        // A mounted share's channel stops the device when it is unmounted
+        SHARES.lock().retain(|share| share.device != device.id);
+    }
+}
@@ -0,0 +1,96 @@
+//! Change notification
+//!
+//! A watch on a directory is signalled when something in it changes that
+//! its filter asks about, and with `subtree`, something anywhere below it.
+//! The VFS reports each change as it makes it: files and directories
+//! created and deleted, data written and security changed. Writes through
+//! an open file are reported as they reach the page cache, not when they
+//! are written back.
+//!
+//! A watch counts the changes it has seen. Its owner waits for the count
+//! to rise, then rearms it with how many it woke for, as
+//! FindNextChangeNotification does; changes that came in between leave it
+//! signalled, so none is missed.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+
+use spin::Mutex;
+
+use super::security::normalize_path;
+
+// What a watch's filter may ask about, with FILE_NOTIFY_CHANGE_*'s values
+pub const CHANGE_FILE_NAME: u32 = 0x001;
+pub const CHANGE_DIR_NAME: u32 = 0x002;
+pub const CHANGE_ATTRIBUTES: u32 = 0x004;
+pub const CHANGE_SIZE: u32 = 0x008;
+pub const CHANGE_LAST_WRITE: u32 = 0x010;
+pub const CHANGE_SECURITY: u32 = 0x100;
+pub const CHANGE_ALL: u32 =
+    CHANGE_FILE_NAME | CHANGE_DIR_NAME | CHANGE_ATTRIBUTES | CHANGE_SIZE | CHANGE_LAST_WRITE | CHANGE_SECURITY;
+
+struct Watch {
+    directory: String,
+    subtree: bool,
+    filter: u32,
+    // Changes since the watch was last rearmed
+    signalled: usize,
+}
+
+static WATCHES: Mutex<BTreeMap<u64, Watch>> = Mutex::new(BTreeMap::new());
+
+/// Watch the directory at global path `directory`
+pub fn watch(directory: &str, subtree: bool, filter: u32) -> u64 {
+    let mut watches = WATCHES.lock();
+    let id = watches.keys().next_back().map_or(1, |last| last + 1);
+    let directory = normalize_path(directory);
+    watches.insert(id, Watch { directory, subtree, filter, signalled: 0 });
+    id
+}
+
+pub fn unwatch(id: u64) -> bool {
+    WATCHES.lock().remove(&id).is_some()
+}
+
+/// How many changes the watch has seen since it was last rearmed, or
+/// None if there is no such watch
+pub fn signalled(id: u64) -> Option<usize> {
+    WATCHES.lock().get(&id).map(|watch| watch.signalled)
+}
+
+/// Take back the `seen` changes the owner woke for, to wait for the next
+pub fn rearm(id: u64, seen: usize) -> bool {
+    match WATCHES.lock().get_mut(&id) {
+        Some(watch) => {
+            watch.signalled = watch.signalled.saturating_sub(seen);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Report a change to the file or directory at global path `path`;
+/// `kinds` are the CHANGE_* bits it counts under
+pub fn changed(path: &str, kinds: u32) {
+    let path = normalize_path(path);
+    let mut watches = WATCHES.lock();
+    for watch in watches.values_mut() {
+        if watch.filter & kinds == 0 {
+            continue;
+        }
+        let Some(name) = relative(&watch.directory, &path) else {
+            continue;
+        };
+        if !watch.subtree && name.contains('/') {
+            continue;
+        }
+        watch.signalled += 1;
+    }
+}
+
+// `path` from `directory`, if it is below it
+fn relative<'a>(directory: &str, path: &'a str) -> Option<&'a str> {
+    let rest = path.strip_prefix(directory)?;
+    let rest = if directory.ends_with('/') { rest } else { rest.strip_prefix('/')? };
+    (!rest.is_empty()).then_some(rest)
+}
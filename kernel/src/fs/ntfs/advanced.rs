@@ -1,12 +1,16 @@
 // NTFS Advanced Features Implementation
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use super::{NtfsFileSystem, MFT_ENTRY_ROOT};
 use super::mft::MftEntry;
 use super::attributes::{
     Attribute, AttributeContent, ATTR_TYPE_REPARSE_POINT,
-    ATTR_TYPE_FILE_NAME, ATTR_TYPE_STANDARD_INFO,
+    ATTR_TYPE_FILE_NAME, ATTR_TYPE_STANDARD_INFO, ATTR_TYPE_DATA,
+    ATTR_FLAG_COMPRESSED, ATTR_FLAG_SPARSE, runs_to_lcns, lcns_to_runs,
 };
+use crate::fs::{FsControl, FsControlReply};
+use crate::fs::unicode;
 
 // Reparse Point Tags
 pub const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA0000003;
@@ -95,6 +99,8 @@ impl NtfsFileSystem {
     
     // Remove a hard link
     pub fn remove_hard_link(&mut self, link_path: &str) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Parse path
         let components: Vec<&str> = link_path.split('\\').filter(|s| !s.is_empty()).collect();
         if components.is_empty() {
@@ -378,7 +384,8 @@ impl NtfsFileSystem {
         // Substitute name offset
         data.extend_from_slice(&0u16.to_le_bytes());
         // Substitute name length
-        let sub_name_len = (target_path.len() * 2) as u16;
+        let target = unicode::encode_utf16le(target_path);
+        let sub_name_len = target.len() as u16;
         data.extend_from_slice(&sub_name_len.to_le_bytes());
         // Print name offset
         let print_offset = 4 + sub_name_len;
@@ -389,14 +396,10 @@ impl NtfsFileSystem {
         data.extend_from_slice(&0u32.to_le_bytes());
         
         // Substitute name (UTF-16)
-        for ch in target_path.chars() {
-            data.extend_from_slice(&(ch as u16).to_le_bytes());
-        }
+        data.extend_from_slice(&target);
         
         // Print name (same as substitute name)
-        for ch in target_path.chars() {
-            data.extend_from_slice(&(ch as u16).to_le_bytes());
-        }
+        data.extend_from_slice(&target);
         
         data
     }
@@ -407,7 +410,8 @@ impl NtfsFileSystem {
         // Substitute name offset
         data.extend_from_slice(&0u16.to_le_bytes());
         // Substitute name length
-        let sub_name_len = (target_path.len() * 2) as u16;
+        let target = unicode::encode_utf16le(target_path);
+        let sub_name_len = target.len() as u16;
         data.extend_from_slice(&sub_name_len.to_le_bytes());
         // Print name offset
         let print_offset = 4 + sub_name_len;
@@ -416,14 +420,10 @@ impl NtfsFileSystem {
         data.extend_from_slice(&sub_name_len.to_le_bytes());
         
         // Substitute name (UTF-16)
-        for ch in target_path.chars() {
-            data.extend_from_slice(&(ch as u16).to_le_bytes());
-        }
+        data.extend_from_slice(&target);
         
         // Print name
-        for ch in target_path.chars() {
-            data.extend_from_slice(&(ch as u16).to_le_bytes());
-        }
+        data.extend_from_slice(&target);
         
         data
     }
@@ -657,14 +657,49 @@ impl NtfsFileSystem {
 
 // Compression Support
 impl NtfsFileSystem {
-    // Enable compression on a file
+    // Enable compression on a file, compressing the data it already has.
+    // On a directory it makes the files later created in it compressed.
     pub fn enable_compression(&mut self, path: &str) -> Result<(), &'static str> {
+        self.set_data_flags(path, ATTR_FLAG_COMPRESSED, 0)
+    }
+    
+    // Disable compression on a file, storing its data as it is
+    pub fn disable_compression(&mut self, path: &str) -> Result<(), &'static str> {
+        self.set_data_flags(path, 0, ATTR_FLAG_COMPRESSED)
+    }
+    
+    pub fn is_compressed(&mut self, path: &str) -> Result<bool, &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        Ok(self.mft.read_entry(entry_num)?.file_attributes & 0x800 != 0)
+    }
+    
+    // Set and clear flags of a file's data attribute, rewriting a
+    // non-resident stream in its new form, and the file attributes that
+    // mirror them
+    fn set_data_flags(&mut self, path: &str, set: u16, clear: u16) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Find file
         let entry_num = self.find_entry_by_path(path)?;
         let mut entry = self.mft.read_entry(entry_num)?;
         
-        // Set compression flag
-        entry.file_attributes |= 0x800; // FILE_ATTRIBUTE_COMPRESSED
+        let position = entry.attributes.iter()
+            .position(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name.is_empty());
+        if let Some(index) = position {
+            let flags = (entry.attributes[index].flags | set) & !clear;
+            if flags != entry.attributes[index].flags {
+                if let AttributeContent::NonResident(ref non_res) = entry.attributes[index].content {
+                    let data = self.read_attribute_data(&entry.attributes[index])?;
+                    self.free_runs(non_res)?;
+                    entry.attributes[index].content = AttributeContent::NonResident(self.write_stream(&data, flags)?);
+                }
+                entry.attributes[index].flags = flags;
+            }
+        }
+        
+        let set = file_attribute_bits(set);
+        let clear = file_attribute_bits(clear);
+        entry.file_attributes = (entry.file_attributes | set) & !clear;
         
         // Update standard information attribute
         for attr in &mut entry.attributes {
@@ -672,7 +707,7 @@ impl NtfsFileSystem {
                 if let AttributeContent::Resident(ref mut data) = attr.content {
                     if data.len() >= 36 {
                         let mut attrs = u32::from_le_bytes([data[32], data[33], data[34], data[35]]);
-                        attrs |= 0x800;
+                        attrs = (attrs | set) & !clear;
                         data[32..36].copy_from_slice(&attrs.to_le_bytes());
                     }
                 }
@@ -680,88 +715,207 @@ impl NtfsFileSystem {
             }
         }
         
-        // Mark data attribute as compressed
-        for attr in &mut entry.attributes {
-            if attr.type_code == super::attributes::ATTR_TYPE_DATA {
-                attr.flags |= 0x0001; // ATTR_IS_COMPRESSED
-                break;
-            }
-        }
-        
         // Write updated entry
         self.mft.write_entry(&mut *self.disk, entry_num, &entry)?;
         
         Ok(())
     }
+}
+
+// FILE_ATTRIBUTE_COMPRESSED and FILE_ATTRIBUTE_SPARSE_FILE for data
+// attribute flags
+fn file_attribute_bits(flags: u16) -> u32 {
+    let mut bits = 0;
+    if flags & ATTR_FLAG_COMPRESSED != 0 {
+        bits |= 0x800;
+    }
+    if flags & ATTR_FLAG_SPARSE != 0 {
+        bits |= 0x200;
+    }
+    bits
+}
+
+// Sparse File Support
+impl NtfsFileSystem {
+    // Mark a file as sparse, freeing the clusters it has that are all zeros
+    pub fn set_sparse(&mut self, path: &str) -> Result<(), &'static str> {
+        self.set_data_flags(path, ATTR_FLAG_SPARSE, 0)
+    }
     
-    // Disable compression on a file
-    pub fn disable_compression(&mut self, path: &str) -> Result<(), &'static str> {
+    // Mark a file as not sparse, giving clusters to the ranges it lacks
+    pub fn clear_sparse(&mut self, path: &str) -> Result<(), &'static str> {
+        self.set_data_flags(path, 0, ATTR_FLAG_SPARSE)
+    }
+    
+    // Allocate a range in a sparse file
+    pub fn allocate_sparse_range(&mut self, path: &str, offset: u64, length: u64) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Find file
         let entry_num = self.find_entry_by_path(path)?;
         let mut entry = self.mft.read_entry(entry_num)?;
+        let cluster_size = self.cluster_size as u64;
         
-        // Clear compression flag
-        entry.file_attributes &= !0x800;
-        
-        // Update standard information attribute
+        // Find data attribute
         for attr in &mut entry.attributes {
-            if attr.type_code == ATTR_TYPE_STANDARD_INFO {
-                if let AttributeContent::Resident(ref mut data) = attr.content {
-                    if data.len() >= 36 {
-                        let mut attrs = u32::from_le_bytes([data[32], data[33], data[34], data[35]]);
-                        attrs &= !0x800;
-                        data[32..36].copy_from_slice(&attrs.to_le_bytes());
+            if attr.type_code == ATTR_TYPE_DATA && attr.name.is_empty() {
+                if let AttributeContent::NonResident(ref mut non_res) = attr.content {
+                    // A compressed unit's clusters hold its compressed form
+                    if non_res.compression_unit != 0 {
+                        return Err("Cannot allocate a range of a compressed file");
                     }
+                    
+                    // Give each sparse cluster in the range one of zeros
+                    let mut lcns = runs_to_lcns(&non_res.data_runs);
+                    let start = (offset / cluster_size) as usize;
+                    let end = ((offset + length + cluster_size - 1) / cluster_size) as usize;
+                    for vcn in start..end.min(lcns.len()) {
+                        if lcns[vcn].is_none() {
+                            let allocated = self.allocate_clusters(1)?;
+                            self.write_clusters(&allocated, &[])?;
+                            lcns[vcn] = Some(allocated[0]);
+                            non_res.compressed_size += cluster_size;
+                        }
+                    }
+                    non_res.data_runs = lcns_to_runs(&lcns);
                 }
                 break;
             }
         }
         
-        // Clear compression flag on data attribute
-        for attr in &mut entry.attributes {
-            if attr.type_code == super::attributes::ATTR_TYPE_DATA {
-                attr.flags &= !0x0001;
-                break;
-            }
-        }
-        
         // Write updated entry
         self.mft.write_entry(&mut *self.disk, entry_num, &entry)?;
         
         Ok(())
     }
+    
+    // Zero the bytes of a file from `offset` up to `end`. In a sparse or
+    // compressed file the clusters left all zeros are freed.
+    pub fn zero_range(&mut self, path: &str, offset: u64, end: u64) -> Result<(), &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        let mut data = self.read_file_data(entry_num)?;
+        let end = end.min(data.len() as u64);
+        if offset < end {
+            data[offset as usize..end as usize].fill(0);
+            self.update_file_data(entry_num, &data)?;
+        }
+        Ok(())
+    }
+    
+    // The parts of a file within `length` bytes from `offset` that have
+    // clusters on disk, as (offset, length). A compression unit with any
+    // clusters counts as allocated throughout.
+    pub fn allocated_ranges(&mut self, path: &str, offset: u64, length: u64) -> Result<Vec<(u64, u64)>, &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        let entry = self.mft.read_entry(entry_num)?;
+        let cluster_size = self.cluster_size as u64;
+        
+        let (size, ranges) = match entry.data_stream("").map(|attr| &attr.content) {
+            None => (0, Vec::new()),
+            Some(AttributeContent::Resident(data)) => (data.len() as u64, vec![(0, data.len() as u64)]),
+            Some(AttributeContent::NonResident(non_res)) => {
+                let unit = 1usize << non_res.compression_unit;
+                let mut ranges: Vec<(u64, u64)> = Vec::new();
+                for (i, lcns) in runs_to_lcns(&non_res.data_runs).chunks(unit).enumerate() {
+                    if lcns.iter().all(|lcn| lcn.is_none()) {
+                        continue;
+                    }
+                    let start = (i * unit) as u64 * cluster_size;
+                    let len = lcns.len() as u64 * cluster_size;
+                    match ranges.last_mut() {
+                        Some(last) if last.0 + last.1 == start => last.1 += len,
+                        _ => ranges.push((start, len)),
+                    }
+                }
+                (non_res.real_size, ranges)
+            }
+        };
+        
+        let end = offset.saturating_add(length).min(size);
+        Ok(ranges
+            .into_iter()
+            .filter_map(|(start, len)| {
+                let from = start.max(offset);
+                let to = (start + len).min(end);
+                if from < to { Some((from, to - from)) } else { None }
+            })
+            .collect())
+    }
 }
 
-// Sparse File Support
+// File System Controls
 impl NtfsFileSystem {
-    // Mark a file as sparse
-    pub fn set_sparse(&mut self, path: &str) -> Result<(), &'static str> {
-        // Find file
+    pub fn fs_control_impl(&mut self, path: &str, request: FsControl) -> Result<FsControlReply, &'static str> {
+        match request {
+            FsControl::GetCompression => Ok(FsControlReply::Compressed(self.is_compressed(path)?)),
+            FsControl::SetCompression(true) => self.enable_compression(path).map(|_| FsControlReply::Done),
+            FsControl::SetCompression(false) => self.disable_compression(path).map(|_| FsControlReply::Done),
+            FsControl::SetSparse(true) => self.set_sparse(path).map(|_| FsControlReply::Done),
+            FsControl::SetSparse(false) => self.clear_sparse(path).map(|_| FsControlReply::Done),
+            FsControl::SetZeroData { offset, end } => self.zero_range(path, offset, end).map(|_| FsControlReply::Done),
+            FsControl::QueryAllocatedRanges { offset, length } => {
+                Ok(FsControlReply::Ranges(self.allocated_ranges(path, offset, length)?))
+            }
+        }
+    }
+}
+
+// Alternate Data Streams
+impl NtfsFileSystem {
+    // The named streams of a file, as (name, size); each is a named $DATA
+    // attribute beside the file's own unnamed one
+    pub fn list_streams_impl(&mut self, path: &str) -> Result<Vec<(String, u64)>, &'static str> {
         let entry_num = self.find_entry_by_path(path)?;
-        let mut entry = self.mft.read_entry(entry_num)?;
+        let entry = self.mft.read_entry(entry_num)?;
         
-        // Set sparse flag
-        entry.file_attributes |= 0x200; // FILE_ATTRIBUTE_SPARSE_FILE
+        Ok(entry.attributes
+            .iter()
+            .filter(|attr| attr.type_code == ATTR_TYPE_DATA && !attr.name.is_empty())
+            .map(|attr| {
+                let size = match &attr.content {
+                    AttributeContent::Resident(data) => data.len() as u64,
+                    AttributeContent::NonResident(non_res) => non_res.real_size,
+                };
+                (attr.name.clone(), size)
+            })
+            .collect())
+    }
+    
+    pub fn read_stream_impl(&mut self, path: &str, stream: &str) -> Result<Vec<u8>, &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        let entry = self.mft.read_entry(entry_num)?;
+        let attr = entry.data_stream(stream).ok_or("Stream not found")?;
+        self.read_attribute_data(attr)
+    }
+    
+    // Replace a named stream's data, creating the stream if need be
+    pub fn write_stream_impl(&mut self, path: &str, stream: &str, data: &[u8]) -> Result<(), &'static str> {
+        if stream.is_empty() || stream.chars().any(|c| matches!(c, ':' | '/' | '\\' | '\0')) {
+            return Err("Invalid stream name");
+        }
         
-        // Update standard information attribute
-        for attr in &mut entry.attributes {
-            if attr.type_code == ATTR_TYPE_STANDARD_INFO {
-                if let AttributeContent::Resident(ref mut data) = attr.content {
-                    if data.len() >= 36 {
-                        let mut attrs = u32::from_le_bytes([data[32], data[33], data[34], data[35]]);
-                        attrs |= 0x200;
-                        data[32..36].copy_from_slice(&attrs.to_le_bytes());
-                    }
+        let entry_num = self.find_entry_by_path(path)?;
+        let mut entry = self.mft.read_entry(entry_num)?;
+        
+        let position = entry.attributes.iter()
+            .position(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name == stream);
+        match position {
+            Some(index) => {
+                if let AttributeContent::NonResident(ref non_res) = entry.attributes[index].content {
+                    self.free_runs(non_res)?;
                 }
-                break;
+                let flags = entry.attributes[index].flags;
+                entry.attributes[index].content = self.data_attribute(data, flags)?.content;
             }
-        }
-        
-        // Mark data attribute as sparse
-        for attr in &mut entry.attributes {
-            if attr.type_code == super::attributes::ATTR_TYPE_DATA {
-                attr.flags |= 0x8000; // ATTR_IS_SPARSE
-                break;
+            None => {
+                let mut attr = self.data_attribute(data, 0)?;
+                attr.name = String::from(stream);
+                
+                // Attributes are kept in order of type, then name
+                let index = entry.attributes.iter()
+                    .position(|a| (a.type_code, a.name.as_str()) > (ATTR_TYPE_DATA, stream))
+                    .unwrap_or(entry.attributes.len());
+                entry.attributes.insert(index, attr);
             }
         }
         
@@ -771,33 +925,16 @@ impl NtfsFileSystem {
         Ok(())
     }
     
-    // Allocate a range in a sparse file
-    pub fn allocate_sparse_range(&mut self, path: &str, offset: u64, length: u64) -> Result<(), &'static str> {
-        // Find file
+    pub fn delete_stream_impl(&mut self, path: &str, stream: &str) -> Result<(), &'static str> {
         let entry_num = self.find_entry_by_path(path)?;
         let mut entry = self.mft.read_entry(entry_num)?;
         
-        // Find data attribute
-        for attr in &mut entry.attributes {
-            if attr.type_code == super::attributes::ATTR_TYPE_DATA {
-                if let AttributeContent::NonResident(ref mut non_res) = attr.content {
-                    // Calculate clusters needed
-                    let start_cluster = offset / self.cluster_size as u64;
-                    let end_cluster = (offset + length + self.cluster_size as u64 - 1) / self.cluster_size as u64;
-                    let clusters_needed = end_cluster - start_cluster;
-                    
-                    // Allocate clusters
-                    let allocated = self.allocate_clusters(clusters_needed)?;
-                    
-                    // Add to data runs
-                    // This is simplified - real implementation would merge runs
-                    non_res.data_runs.push(super::attributes::DataRun {
-                        length: clusters_needed,
-                        start_lcn: allocated[0],
-                    });
-                }
-                break;
-            }
+        let index = entry.attributes.iter()
+            .position(|attr| attr.type_code == ATTR_TYPE_DATA && !stream.is_empty() && attr.name == stream)
+            .ok_or("Stream not found")?;
+        let attr = entry.attributes.remove(index);
+        if let AttributeContent::NonResident(ref non_res) = attr.content {
+            self.free_runs(non_res)?;
         }
         
         // Write updated entry
@@ -805,4 +942,4 @@ impl NtfsFileSystem {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
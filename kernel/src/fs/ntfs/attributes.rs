@@ -1,8 +1,10 @@
 // NTFS Attributes Implementation
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::collections::BTreeMap;
 use spin::Mutex;
+use crate::fs::unicode;
 
 // Standard NTFS Attribute Types
 pub const ATTR_TYPE_STANDARD_INFO: u32 = 0x10;
@@ -23,6 +25,13 @@ pub const ATTR_TYPE_PROPERTY_SET: u32 = 0xF0;
 pub const ATTR_TYPE_LOGGED_UTIL_STREAM: u32 = 0x100;
 pub const ATTR_TYPE_END: u32 = 0xFFFFFFFF;
 
+// Attribute Flags
+pub const ATTR_FLAG_COMPRESSED: u16 = 0x0001;
+pub const ATTR_FLAG_SPARSE: u16 = 0x8000;
+
+// Compression units are 2^4 = 16 clusters, the only size Windows uses
+pub const COMPRESSION_UNIT: u16 = 4;
+
 // Attribute Header (common part)
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
@@ -67,6 +76,7 @@ pub struct NonResidentAttributeHeader {
 pub struct DataRun {
     pub length: u64,      // Number of clusters
     pub start_lcn: u64,   // Logical Cluster Number
+    pub sparse: bool,     // No clusters on disk; reads as zeros
 }
 
 // Attribute
@@ -91,6 +101,8 @@ pub struct NonResidentAttribute {
     pub allocated_size: u64,
     pub real_size: u64,
     pub initialized_size: u64,
+    pub compression_unit: u16,    // Log2 of clusters per unit; 0 if not compressed
+    pub compressed_size: u64,     // Clusters on disk, in bytes; compressed or sparse only
     pub data_runs: Vec<DataRun>,
 }
 
@@ -154,7 +166,7 @@ fn parse_attribute(data: &[u8]) -> Result<Attribute, &'static str> {
         let name_len = header.name_length as usize * 2; // UTF-16
         
         if name_offset + name_len <= data.len() {
-            parse_utf16_name(&data[name_offset..name_offset + name_len])
+            unicode::decode_utf16le(&data[name_offset..name_offset + name_len])
         } else {
             String::new()
         }
@@ -213,6 +225,7 @@ fn parse_non_resident_content(data: &[u8], header: &AttributeHeader) -> Result<A
     ]);
     
     let data_runs_offset = u16::from_le_bytes([data[32], data[33]]);
+    let compression_unit = u16::from_le_bytes([data[34], data[35]]);
     
     let allocated_size = u64::from_le_bytes([
         data[40], data[41], data[42], data[43],
@@ -229,6 +242,16 @@ fn parse_non_resident_content(data: &[u8], header: &AttributeHeader) -> Result<A
         data[60], data[61], data[62], data[63],
     ]);
     
+    // Compressed and sparse attributes have a longer header
+    let compressed_size = if header.flags & (ATTR_FLAG_COMPRESSED | ATTR_FLAG_SPARSE) != 0 && data.len() >= 72 {
+        u64::from_le_bytes([
+            data[64], data[65], data[66], data[67],
+            data[68], data[69], data[70], data[71],
+        ])
+    } else {
+        0
+    };
+    
     // Parse data runs
     let data_runs = if data_runs_offset > 0 {
         parse_data_runs(&data[data_runs_offset as usize..])?
@@ -242,6 +265,8 @@ fn parse_non_resident_content(data: &[u8], header: &AttributeHeader) -> Result<A
         allocated_size,
         real_size,
         initialized_size,
+        compression_unit,
+        compressed_size,
         data_runs,
     }))
 }
@@ -292,27 +317,91 @@ fn parse_data_runs(data: &[u8]) -> Result<Vec<DataRun>, &'static str> {
         runs.push(DataRun {
             length,
             start_lcn: current_lcn as u64,
+            sparse: offset_bytes == 0,
         });
     }
     
     Ok(runs)
 }
 
-fn parse_utf16_name(data: &[u8]) -> String {
-    let mut name = String::new();
-    
-    for i in (0..data.len()).step_by(2) {
-        if i + 1 < data.len() {
-            let ch = u16::from_le_bytes([data[i], data[i + 1]]);
-            if let Some(c) = char::from_u32(ch as u32) {
-                name.push(c);
+// The LCN behind each VCN of a stream's runs, None where it is sparse
+pub fn runs_to_lcns(runs: &[DataRun]) -> Vec<Option<u64>> {
+    let mut lcns = Vec::new();
+    for run in runs {
+        for i in 0..run.length {
+            lcns.push(if run.sparse { None } else { Some(run.start_lcn + i) });
+        }
+    }
+    lcns
+}
+
+// Runs for a stream's VCNs, merging neighbours contiguous on disk
+pub fn lcns_to_runs(lcns: &[Option<u64>]) -> Vec<DataRun> {
+    let mut runs: Vec<DataRun> = Vec::new();
+    for lcn in lcns {
+        if let Some(last) = runs.last_mut() {
+            let follows = match lcn {
+                Some(lcn) => !last.sparse && last.start_lcn + last.length == *lcn,
+                None => last.sparse,
+            };
+            if follows {
+                last.length += 1;
+                continue;
             }
         }
+        runs.push(DataRun {
+            length: 1,
+            start_lcn: lcn.unwrap_or(0),
+            sparse: lcn.is_none(),
+        });
+    }
+    runs
+}
+
+// Encode data runs as a mapping pairs array: each run's length and its LCN
+// relative to the run before, in as few bytes as hold them signed; sparse
+// runs have no LCN at all
+pub fn encode_data_runs(runs: &[DataRun]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut previous_lcn = 0i64;
+    
+    for run in runs {
+        let length = signed_bytes(run.length as i64);
+        let delta = if run.sparse {
+            Vec::new()
+        } else {
+            let lcn = run.start_lcn as i64;
+            let delta = signed_bytes(lcn - previous_lcn);
+            previous_lcn = lcn;
+            delta
+        };
+        
+        encoded.push(((delta.len() as u8) << 4) | length.len() as u8);
+        encoded.extend_from_slice(&length);
+        encoded.extend_from_slice(&delta);
     }
     
-    name
+    encoded.push(0);
+    encoded
+}
+
+fn signed_bytes(value: i64) -> Vec<u8> {
+    let bytes = value.to_le_bytes();
+    let mut count = 8;
+    // Drop high bytes that only repeat the sign of the byte below
+    while count > 1 {
+        let top = bytes[count - 1];
+        let sign = bytes[count - 2] & 0x80;
+        if (top == 0 && sign == 0) || (top == 0xFF && sign != 0) {
+            count -= 1;
+        } else {
+            break;
+        }
+    }
+    bytes[..count].to_vec()
 }
 
+
 // Write Support Functions
 
 pub fn create_standard_info_attribute(created: u64, modified: u64, accessed: u64, file_attrs: u32) -> Attribute {
@@ -352,8 +441,9 @@ pub fn create_standard_info_attribute(created: u64, modified: u64, accessed: u64
 }
 
 pub fn create_file_name_attribute(parent_ref: u64, name: &str, is_directory: bool) -> Attribute {
-    let name_len = name.len();
-    let mut data = vec![0u8; 66 + name_len * 2];
+    let encoded = unicode::encode_utf16le(name);
+    let name_len = encoded.len() / 2;
+    let mut data = vec![0u8; 66 + encoded.len()];
     
     // Parent directory reference
     data[0..8].copy_from_slice(&parent_ref.to_le_bytes());
@@ -389,11 +479,7 @@ pub fn create_file_name_attribute(parent_ref: u64, name: &str, is_directory: boo
     data[65] = 1;
     
     // Write file name as UTF-16
-    for (i, ch) in name.chars().enumerate() {
-        let offset = 66 + i * 2;
-        let utf16 = ch as u16;
-        data[offset..offset + 2].copy_from_slice(&utf16.to_le_bytes());
-    }
+    data[66..].copy_from_slice(&encoded);
     
     Attribute {
         type_code: ATTR_TYPE_FILE_NAME,
@@ -432,6 +518,7 @@ fn create_non_resident_data_attribute(name: String, data: Vec<u8>) -> Attribute
     data_runs.push(DataRun {
         length: clusters_needed as u64,
         start_lcn: 0, // Would need to allocate actual clusters
+        sparse: false,
     });
     
     let non_res = NonResidentAttribute {
@@ -440,6 +527,8 @@ fn create_non_resident_data_attribute(name: String, data: Vec<u8>) -> Attribute
         allocated_size: (clusters_needed * cluster_size) as u64,
         real_size: data.len() as u64,
         initialized_size: data.len() as u64,
+        compression_unit: 0,
+        compressed_size: 0,
         data_runs,
     };
     
@@ -465,9 +554,12 @@ pub fn update_attribute_data(attr: &mut Attribute, new_data: Vec<u8>) -> Result<
                     allocated_size: ((new_data.len() + 4095) / 4096 * 4096) as u64,
                     real_size: new_data.len() as u64,
                     initialized_size: new_data.len() as u64,
+                    compression_unit: 0,
+                    compressed_size: 0,
                     data_runs: vec![DataRun {
                         length: ((new_data.len() + 4095) / 4096) as u64,
                         start_lcn: 0, // Would need actual allocation
+                        sparse: false,
                     }],
                 });
                 Ok(())
@@ -506,9 +598,12 @@ pub fn resize_attribute(attr: &mut Attribute, new_size: u64) -> Result<(), &'sta
                     allocated_size: clusters * 4096,
                     real_size: new_size,
                     initialized_size: 0,
+                    compression_unit: 0,
+                    compressed_size: 0,
                     data_runs: vec![DataRun {
                         length: clusters,
                         start_lcn: 0, // Would need actual allocation
+                        sparse: false,
                     }],
                 });
                 Ok(())
@@ -3,6 +3,20 @@ use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::collections::BTreeMap;
 use core::cmp::Ordering;
+use crate::fs::unicode::{self, UpCase};
+use super::DirectoryEntry;
+
+// The flag on the entry that ends a node, which has no key
+const INDEX_ENTRY_END: u16 = 0x02;
+
+// Where the node header sits in INDEX_ROOT and in an INDX block
+const ROOT_NODE_HEADER: usize = 16;
+const BLOCK_NODE_HEADER: usize = 0x18;
+
+// A $FILE_NAME key's namespace; a DOS name is the 8.3 alias of a Win32
+// name listed beside it
+const NAMESPACE_DOS: u8 = 2;
+const FILE_NAME_IS_DIRECTORY: u32 = 0x1000_0000;
 
 // Index structures for directory entries
 pub struct IndexRoot {
@@ -190,23 +204,20 @@ impl FileNameIndexEntry {
         data.extend_from_slice(&self.file_attributes.to_le_bytes());
         // EA size and reparse tag
         data.extend_from_slice(&0u32.to_le_bytes());
-        // File name length
-        data.push(self.file_name.len() as u8);
+        // File name length, in UTF-16 units
+        data.push(unicode::utf16_len(&self.file_name) as u8);
         // File name type (1 = Windows)
         data.push(1);
         
         // File name as UTF-16
-        for ch in self.file_name.chars() {
-            let utf16 = ch as u16;
-            data.extend_from_slice(&utf16.to_le_bytes());
-        }
+        data.extend_from_slice(&unicode::encode_utf16le(&self.file_name));
         
         data
     }
     
     pub fn compare(&self, other: &Self) -> Ordering {
         // Case-insensitive comparison for NTFS
-        self.file_name.to_uppercase().cmp(&other.file_name.to_uppercase())
+        UpCase::standard().compare(&self.file_name, &other.file_name)
     }
 }
 
@@ -257,7 +268,7 @@ impl DirectoryIndexTree {
         // Find and remove entry
         let mut found_index = None;
         for (i, entry) in self.root.entries.iter().enumerate() {
-            if entry.file_name.eq_ignore_ascii_case(file_name) {
+            if UpCase::standard().eq_ignore_case(&entry.file_name, file_name) {
                 found_index = Some(i);
                 break;
             }
@@ -279,7 +290,7 @@ impl DirectoryIndexTree {
     
     pub fn find(&self, file_name: &str) -> Option<&FileNameIndexEntry> {
         for entry in &self.root.entries {
-            if entry.file_name.eq_ignore_ascii_case(file_name) {
+            if UpCase::standard().eq_ignore_case(&entry.file_name, file_name) {
                 return Some(entry);
             }
         }
@@ -320,4 +331,88 @@ impl DirectoryIndexTree {
         
         data
     }
-}
\ No newline at end of file
+}
+// The entries of the index node whose header is at `header`
+fn parse_node(data: &[u8], header: usize) -> Vec<DirectoryEntry> {
+    let mut entries = Vec::new();
+    let Some(node) = data.get(header..header + 16) else {
+        return entries;
+    };
+    let first = u32::from_le_bytes([node[0], node[1], node[2], node[3]]) as usize;
+    let end = (header + u32::from_le_bytes([node[4], node[5], node[6], node[7]]) as usize).min(data.len());
+
+    let mut offset = header + first;
+    while offset + 16 <= end {
+        let Ok(entry) = IndexEntry::parse(&data[offset..end]) else {
+            break;
+        };
+        if entry.flags & INDEX_ENTRY_END != 0 || entry.length < 16 {
+            break;
+        }
+        offset += entry.length as usize;
+
+        // The key is the file's $FILE_NAME: sizes at 40, attributes at 56,
+        // then the name's length in units, its namespace and the name
+        let key = &entry.key;
+        if key.len() < 66 || key[65] == NAMESPACE_DOS {
+            continue;
+        }
+        let units = key[64] as usize;
+        let Some(name) = key.get(66..66 + units * 2) else {
+            continue;
+        };
+        let attributes = u32::from_le_bytes([key[56], key[57], key[58], key[59]]);
+        entries.push(DirectoryEntry {
+            name: unicode::decode_utf16le(name),
+            mft_reference: entry.file_reference & 0xFFFF_FFFF_FFFF,
+            is_directory: attributes & FILE_NAME_IS_DIRECTORY != 0,
+            size: u64::from_le_bytes(key[48..56].try_into().unwrap()),
+        });
+    }
+    entries
+}
+
+/// The entries in an INDEX_ROOT attribute's value
+pub fn parse_root_entries(data: &[u8]) -> Vec<DirectoryEntry> {
+    parse_node(data, ROOT_NODE_HEADER)
+}
+
+/// The entries in the INDX blocks of an INDEX_ALLOCATION attribute, each
+/// `block_size` bytes
+pub fn parse_allocation_entries(data: &[u8], block_size: usize) -> Vec<DirectoryEntry> {
+    let mut entries = Vec::new();
+    for block in data.chunks_exact(block_size.max(512)) {
+        if &block[0..4] != b"INDX" {
+            continue;
+        }
+        let mut block = block.to_vec();
+        if apply_fixups(&mut block) {
+            entries.extend(parse_node(&block, BLOCK_NODE_HEADER));
+        }
+    }
+    entries
+}
+
+// Put back the last two bytes of each sector, which the update sequence
+// array holds while the block is on disk; false if a sector was torn
+fn apply_fixups(block: &mut [u8]) -> bool {
+    let offset = u16::from_le_bytes([block[4], block[5]]) as usize;
+    let count = u16::from_le_bytes([block[6], block[7]]) as usize;
+    if count == 0 || offset + count * 2 > block.len() {
+        return false;
+    }
+    let check = [block[offset], block[offset + 1]];
+    for sector in 1..count {
+        let end = sector * 512;
+        if end > block.len() {
+            return false;
+        }
+        if block[end - 2..end] != check {
+            return false;
+        }
+        let value = offset + sector * 2;
+        block[end - 2] = block[value];
+        block[end - 1] = block[value + 1];
+    }
+    true
+}
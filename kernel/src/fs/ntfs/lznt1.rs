@@ -0,0 +1,230 @@
+// LZNT1, the compression NTFS uses for compressed attributes
+//
+// A compression unit's data is cut into 4 KB chunks, each behind a two
+// byte header: bit 15 set if the chunk is compressed, bits 12-14 always 3,
+// and the low 12 bits its stored size less one. A compressed chunk is
+// groups of up to eight tokens, each group led by a byte with a bit per
+// token: clear for a literal byte, set for a two byte back-reference. How
+// a back-reference's bits split between offset and length depends on how
+// far into the chunk it is, the offset taking more as the chunk fills.
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const CHUNK_SIZE: usize = 4096;
+
+const CHUNK_COMPRESSED: u16 = 0x8000;
+const CHUNK_SIGNATURE: u16 = 0x3000;
+const CHUNK_SIZE_MASK: u16 = 0x0FFF;
+const MIN_MATCH: usize = 3;
+const HASH_SIZE: usize = 4096;
+// Earlier positions tried for each back-reference
+const MAX_CANDIDATES: usize = 64;
+
+// Bits of a back-reference given to its offset, for one at `position`
+fn offset_bits(position: usize) -> u32 {
+    let mut bits = 4;
+    let mut i = position - 1;
+    while i >= 0x10 {
+        i >>= 1;
+        bits += 1;
+    }
+    bits
+}
+
+// Decompress a compression unit into `size` bytes; whatever the chunks do
+// not cover reads as zeros
+pub fn decompress(input: &[u8], size: usize) -> Result<Vec<u8>, &'static str> {
+    let mut output = Vec::with_capacity(size);
+    let mut offset = 0;
+
+    while offset + 2 <= input.len() && output.len() < size {
+        let header = u16::from_le_bytes([input[offset], input[offset + 1]]);
+        if header == 0 {
+            break;
+        }
+
+        let length = (header & CHUNK_SIZE_MASK) as usize + 1;
+        let chunk = input.get(offset + 2..offset + 2 + length).ok_or("LZNT1 chunk runs past its unit")?;
+        offset += 2 + length;
+
+        let chunk_start = output.len();
+        if header & CHUNK_COMPRESSED == 0 {
+            output.extend_from_slice(chunk);
+        } else {
+            decompress_chunk(chunk, &mut output)?;
+        }
+
+        // A chunk that decompresses short is padded out with zeros
+        if output.len() < chunk_start + CHUNK_SIZE {
+            output.resize(chunk_start + CHUNK_SIZE, 0);
+        }
+    }
+
+    output.resize(size, 0);
+    Ok(output)
+}
+
+fn decompress_chunk(chunk: &[u8], output: &mut Vec<u8>) -> Result<(), &'static str> {
+    let start = output.len();
+    let mut offset = 0;
+
+    while offset < chunk.len() {
+        let flags = chunk[offset];
+        offset += 1;
+
+        for bit in 0..8 {
+            if offset >= chunk.len() {
+                break;
+            }
+
+            if flags & (1 << bit) == 0 {
+                output.push(chunk[offset]);
+                offset += 1;
+                continue;
+            }
+
+            let token = chunk.get(offset..offset + 2).ok_or("LZNT1 back-reference cut short")?;
+            let token = u16::from_le_bytes([token[0], token[1]]);
+            offset += 2;
+
+            let position = output.len() - start;
+            if position == 0 {
+                return Err("LZNT1 back-reference before any data");
+            }
+            let bits = offset_bits(position);
+            let back = (token >> (16 - bits)) as usize + 1;
+            let length = (token & (0xFFFF >> bits)) as usize + MIN_MATCH;
+            if back > position || position + length > CHUNK_SIZE {
+                return Err("LZNT1 back-reference out of range");
+            }
+
+            // Byte by byte, as a reference may overlap what it produces
+            for _ in 0..length {
+                output.push(output[output.len() - back]);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Compress up to a compression unit of data. A chunk that would not
+// shrink is stored as it is, always at full size.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    for chunk in input.chunks(CHUNK_SIZE) {
+        let packed = compress_chunk(chunk);
+        if packed.len() < CHUNK_SIZE {
+            let header = CHUNK_COMPRESSED | CHUNK_SIGNATURE | (packed.len() - 1) as u16;
+            output.extend_from_slice(&header.to_le_bytes());
+            output.extend_from_slice(&packed);
+        } else {
+            let header = CHUNK_SIGNATURE | (CHUNK_SIZE - 1) as u16;
+            output.extend_from_slice(&header.to_le_bytes());
+            output.extend_from_slice(chunk);
+            output.resize(output.len() + CHUNK_SIZE - chunk.len(), 0);
+        }
+    }
+
+    output
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    let (a, b, c) = (data[position] as usize, data[position + 1] as usize, data[position + 2] as usize);
+    ((a << 8) ^ (b << 4) ^ c) & (HASH_SIZE - 1)
+}
+
+// Hash chains over a chunk: the latest position each three byte prefix
+// was seen at, and for each position the one before it
+struct Matcher {
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl Matcher {
+    fn new(len: usize) -> Self {
+        Matcher { head: vec![usize::MAX; HASH_SIZE], previous: vec![usize::MAX; len] }
+    }
+
+    fn insert(&mut self, data: &[u8], position: usize) {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(data, position);
+            self.previous[position] = self.head[h];
+            self.head[h] = position;
+        }
+    }
+
+    // The longest earlier match for `position` a back-reference there can
+    // express, as (distance, length)
+    fn longest(&self, data: &[u8], position: usize) -> (usize, usize) {
+        if position == 0 || position + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+
+        let bits = offset_bits(position);
+        let max_back = 1usize << bits;
+        let max_length = ((0xFFFF >> bits) as usize + MIN_MATCH).min(data.len() - position);
+        let mut best = (0, 0);
+        let mut candidate = self.head[hash(data, position)];
+        let mut tried = 0;
+
+        while candidate != usize::MAX && tried < MAX_CANDIDATES {
+            let back = position - candidate;
+            if back > max_back {
+                break;
+            }
+
+            let mut length = 0;
+            while length < max_length && data[candidate + length] == data[position + length] {
+                length += 1;
+            }
+            if length > best.1 {
+                best = (back, length);
+                if length == max_length {
+                    break;
+                }
+            }
+
+            candidate = self.previous[candidate];
+            tried += 1;
+        }
+
+        best
+    }
+}
+
+fn compress_chunk(chunk: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(chunk.len());
+    let mut matcher = Matcher::new(chunk.len());
+    let mut position = 0;
+
+    while position < chunk.len() {
+        let flags_at = output.len();
+        output.push(0);
+
+        for bit in 0..8 {
+            if position >= chunk.len() {
+                break;
+            }
+
+            let (back, length) = matcher.longest(chunk, position);
+            if length >= MIN_MATCH {
+                let bits = offset_bits(position);
+                let token = (((back - 1) as u16) << (16 - bits)) | (length - MIN_MATCH) as u16;
+                output.extend_from_slice(&token.to_le_bytes());
+                output[flags_at] |= 1 << bit;
+                for p in position..position + length {
+                    matcher.insert(chunk, p);
+                }
+                position += length;
+            } else {
+                output.push(chunk[position]);
+                matcher.insert(chunk, position);
+                position += 1;
+            }
+        }
+    }
+
+    output
+}
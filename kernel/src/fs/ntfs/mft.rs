@@ -6,8 +6,10 @@ use alloc::collections::BTreeMap;
 use alloc::boxed::Box;
 use spin::Mutex;
 use crate::drivers::disk::DiskDriver;
+use crate::fs::unicode;
 use super::boot_sector::NtfsBootSector;
-use super::attributes::{Attribute, AttributeContent, parse_attributes, ATTR_TYPE_BITMAP};
+use super::attributes::{Attribute, AttributeContent, parse_attributes, encode_data_runs, ATTR_TYPE_BITMAP};
+use super::attributes::{ATTR_TYPE_DATA, ATTR_FLAG_COMPRESSED, ATTR_FLAG_SPARSE};
 use super::journal::{JournalManager, OperationType};
 
 // MFT Entry Header
@@ -181,6 +183,11 @@ impl MftEntry {
         self.attributes.iter().find(|attr| attr.type_code == type_code)
     }
     
+    // A $DATA attribute by stream name; the file's own data is unnamed
+    pub fn data_stream(&self, name: &str) -> Option<&Attribute> {
+        self.attributes.iter().find(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name == name)
+    }
+    
     pub fn get_file_name(&self) -> Option<String> {
         for attr in &self.attributes {
             if attr.type_code == super::attributes::ATTR_TYPE_FILE_NAME {
@@ -191,16 +198,7 @@ impl MftEntry {
                         let name_type = data[65];
                         
                         if data.len() >= 66 + name_len * 2 {
-                            // Convert UTF-16 to String
-                            let mut name = String::new();
-                            for i in 0..name_len {
-                                let offset = 66 + i * 2;
-                                let ch = u16::from_le_bytes([data[offset], data[offset + 1]]);
-                                if let Some(c) = char::from_u32(ch as u32) {
-                                    name.push(c);
-                                }
-                            }
-                            return Some(name);
+                            return Some(unicode::decode_utf16le(&data[66..66 + name_len * 2]));
                         }
                     }
                 }
@@ -534,10 +532,29 @@ impl MasterFileTable {
         // Write type
         data.extend_from_slice(&attr.type_code.to_le_bytes());
         
+        // Compressed and sparse attributes carry their compressed size too
+        let header_len = match &attr.content {
+            AttributeContent::Resident(_) => 24,
+            AttributeContent::NonResident(_) if attr.flags & (ATTR_FLAG_COMPRESSED | ATTR_FLAG_SPARSE) != 0 => 72,
+            AttributeContent::NonResident(_) => 64,
+        };
+        let runs = match &attr.content {
+            AttributeContent::Resident(_) => Vec::new(),
+            AttributeContent::NonResident(non_res) => encode_data_runs(&non_res.data_runs),
+        };
+        
+        // The name, in UTF-16, follows the header; the value or runs follow
+        // the name
+        let name: Vec<u16> = attr.name.encode_utf16().collect();
+        if name.len() > 255 {
+            return Err("Attribute name too long");
+        }
+        let value_offset = (header_len + name.len() * 2 + 7) & !7;
+        
         // Calculate and write length
         let content_len = match &attr.content {
-            AttributeContent::Resident(d) => 24 + d.len(),
-            AttributeContent::NonResident(_) => 64,
+            AttributeContent::Resident(d) => value_offset + d.len(),
+            AttributeContent::NonResident(_) => value_offset + runs.len(),
         };
         
         let total_len = ((content_len + 7) & !7) as u32; // Align to 8 bytes
@@ -550,8 +567,8 @@ impl MasterFileTable {
         }
         
         // Write name length and offset
-        data.push(0); // name_length
-        data.extend_from_slice(&[0u8; 2]); // name_offset
+        data.push(name.len() as u8); // name_length
+        data.extend_from_slice(&(header_len as u16).to_le_bytes()); // name_offset
         
         // Write flags and attribute ID
         data.extend_from_slice(&attr.flags.to_le_bytes());
@@ -562,31 +579,37 @@ impl MasterFileTable {
             AttributeContent::Resident(content_data) => {
                 // Write resident header
                 data.extend_from_slice(&(content_data.len() as u32).to_le_bytes());
-                data.extend_from_slice(&(24u16).to_le_bytes()); // value_offset
+                data.extend_from_slice(&(value_offset as u16).to_le_bytes()); // value_offset
                 data.extend_from_slice(&[0u8; 2]); // indexed_flag and padding
-                
-                // Write data
-                data.extend_from_slice(content_data);
             }
             AttributeContent::NonResident(non_res) => {
                 // Write non-resident header
                 data.extend_from_slice(&non_res.start_vcn.to_le_bytes());
                 data.extend_from_slice(&non_res.last_vcn.to_le_bytes());
-                data.extend_from_slice(&(64u16).to_le_bytes()); // data_runs_offset
-                data.extend_from_slice(&[0u8; 2]); // compression_unit_size
+                data.extend_from_slice(&(value_offset as u16).to_le_bytes()); // data_runs_offset
+                data.extend_from_slice(&non_res.compression_unit.to_le_bytes()); // compression_unit_size
                 data.extend_from_slice(&[0u8; 4]); // padding
                 data.extend_from_slice(&non_res.allocated_size.to_le_bytes());
                 data.extend_from_slice(&non_res.real_size.to_le_bytes());
                 data.extend_from_slice(&non_res.initialized_size.to_le_bytes());
-                
-                // Write data runs
-                for run in &non_res.data_runs {
-                    // Simplified data run encoding
-                    // Real implementation would properly encode runs
+                if header_len == 72 {
+                    data.extend_from_slice(&non_res.compressed_size.to_le_bytes());
                 }
             }
         }
         
+        // Write name
+        for unit in &name {
+            data.extend_from_slice(&unit.to_le_bytes());
+        }
+        data.resize(value_offset, 0);
+        
+        // Write data or data runs
+        match &attr.content {
+            AttributeContent::Resident(content_data) => data.extend_from_slice(content_data),
+            AttributeContent::NonResident(_) => data.extend_from_slice(&runs),
+        }
+        
         // Pad to 8-byte alignment
         while data.len() < total_len as usize {
             data.push(0);
@@ -7,6 +7,7 @@ pub mod security;
 pub mod journal;
 pub mod write_ops;
 pub mod advanced;
+pub mod lznt1;
 
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -16,11 +17,16 @@ use alloc::vec;
 use spin::Mutex;
 use crate::drivers::disk::DiskDriver;
 use self::journal::JournalManager;
+use super::unicode::UpCase;
 
 // NTFS Constants
 pub const NTFS_SIGNATURE: &[u8; 8] = b"NTFS    ";
 pub const SECTOR_SIZE: usize = 512;
 pub const MFT_ENTRY_SIZE: usize = 1024;
+// Files whose data runs are kept for ranged reads
+pub const OPEN_STREAMS: usize = 64;
+// INDX block size for a directory whose INDEX_ROOT does not say
+const DEFAULT_INDEX_BLOCK_SIZE: usize = 4096;
 
 // NTFS System Files (first 16 MFT entries)
 pub const MFT_ENTRY_MFT: u64 = 0;        // $MFT
@@ -66,6 +72,68 @@ pub struct NtfsFileSystem {
     volume_info: VolumeInfo,
     journal: Option<Box<JournalManager>>,
     cluster_bitmap: Mutex<ClusterBitmap>,
+    secure: Mutex<security::SecureStream>,
+    open_streams: BTreeMap<String, StreamMap>,
+    // The volume's $UpCase, if it could be read
+    upcase_table: Option<UpCase>,
+}
+
+// Where a file's data is, kept per path for ranged reads so that each one
+// does not walk the directories and decode the runs again
+enum StreamMap {
+    Resident(Vec<u8>),
+    NonResident {
+        size: u64,
+        initialized_size: u64,
+        compression_unit: u16,
+        // (first VCN, clusters, first LCN), in VCN order; no LCN if sparse
+        extents: Vec<(u64, u64, Option<u64>)>,
+        // The compression unit last read, by number, as reads a page at a
+        // time come several to a unit
+        last_unit: Option<(u64, Vec<u8>)>,
+    },
+}
+
+impl StreamMap {
+    fn new(attr: &attributes::Attribute) -> Self {
+        match &attr.content {
+            attributes::AttributeContent::Resident(data) => StreamMap::Resident(data.clone()),
+            attributes::AttributeContent::NonResident(non_res) => {
+                let mut vcn = non_res.start_vcn;
+                let mut extents = Vec::with_capacity(non_res.data_runs.len());
+                for run in &non_res.data_runs {
+                    extents.push((vcn, run.length, if run.sparse { None } else { Some(run.start_lcn) }));
+                    vcn += run.length;
+                }
+                StreamMap::NonResident {
+                    size: non_res.real_size,
+                    initialized_size: non_res.initialized_size,
+                    compression_unit: non_res.compression_unit,
+                    extents,
+                    last_unit: None,
+                }
+            }
+        }
+    }
+    
+    fn size(&self) -> u64 {
+        match self {
+            StreamMap::Resident(data) => data.len() as u64,
+            StreamMap::NonResident { size, .. } => *size,
+        }
+    }
+}
+
+// The extent holding `vcn`, found by binary search, as (LCN of `vcn`,
+// clusters left in the extent from it). Past the last run there is
+// nothing on disk.
+fn lookup_vcn(extents: &[(u64, u64, Option<u64>)], vcn: u64) -> Option<(Option<u64>, u64)> {
+    let index = extents.partition_point(|&(first, length, _)| first + length <= vcn);
+    let &(first, length, lcn) = extents.get(index)?;
+    if first > vcn {
+        return None;
+    }
+    Some((lcn.map(|lcn| lcn + vcn - first), first + length - vcn))
 }
 
 // Cluster allocation bitmap
@@ -180,7 +248,7 @@ impl NtfsFileSystem {
         // Journal initialization would go here
         let journal = None;
         
-        Ok(Self {
+        let mut fs = Self {
             disk,
             boot_sector,
             mft,
@@ -189,7 +257,17 @@ impl NtfsFileSystem {
             volume_info,
             journal,
             cluster_bitmap,
-        })
+            secure: Mutex::new(security::SecureStream::new()),
+            open_streams: BTreeMap::new(),
+            upcase_table: None,
+        };
+        
+        // Volumes without a readable $Secure start with an empty store
+        let _ = fs.load_secure_stream();
+        // and those without a readable $UpCase compare names by the standard table
+        fs.upcase_table = fs.read_file_data(MFT_ENTRY_UPCASE).ok().and_then(|data| UpCase::parse(&data));
+        
+        Ok(fs)
     }
     
     fn read_volume_info(disk: &mut dyn DiskDriver, mft: &mft::MasterFileTable) -> Result<VolumeInfo, &'static str> {
@@ -207,7 +285,20 @@ impl NtfsFileSystem {
         })
     }
     
+    // The table names are compared through without case
+    fn upcase(&self) -> &UpCase {
+        self.upcase_table.as_ref().unwrap_or(UpCase::standard())
+    }
+    
     pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, &'static str> {
+        let entry_num = self.find_file_entry(path)?;
+        
+        // Read file data
+        self.read_file_data(entry_num)
+    }
+    
+    // The MFT entry of the file at `path`
+    fn find_file_entry(&mut self, path: &str) -> Result<u64, &'static str> {
         // Parse path
         let components: Vec<&str> = path.split('\\').filter(|s| !s.is_empty()).collect();
         
@@ -234,8 +325,7 @@ impl NtfsFileSystem {
             }
         }
         
-        // Read file data
-        self.read_file_data(current_entry)
+        Ok(current_entry)
     }
     
     fn find_in_directory(&mut self, dir_entry: &mft::MftEntry, name: &str, is_dir: bool) -> Result<u64, &'static str> {
@@ -244,7 +334,7 @@ impl NtfsFileSystem {
         
         // Search for matching name
         for entry in index_entries {
-            if entry.name.eq_ignore_ascii_case(name) {
+            if self.upcase().eq_ignore_case(&entry.name, name) {
                 if is_dir && !entry.is_directory {
                     return Err("Not a directory");
                 }
@@ -260,18 +350,22 @@ impl NtfsFileSystem {
     
     fn read_directory_entries(&mut self, dir_entry: &mft::MftEntry) -> Result<Vec<DirectoryEntry>, &'static str> {
         let mut entries = Vec::new();
+        let mut block_size = DEFAULT_INDEX_BLOCK_SIZE;
         
         // Read INDEX_ROOT attribute
         if let Some(index_root) = dir_entry.get_attribute(attributes::ATTR_TYPE_INDEX_ROOT) {
             // Parse index entries
             let index_data = self.read_attribute_data(index_root)?;
-            entries.extend(self.parse_index_entries(&index_data)?);
+            if let Ok(root) = index::IndexRoot::parse(&index_data) {
+                block_size = root.index_block_size as usize;
+            }
+            entries.extend(index::parse_root_entries(&index_data));
         }
         
         // Read INDEX_ALLOCATION attribute for large directories
         if let Some(index_alloc) = dir_entry.get_attribute(attributes::ATTR_TYPE_INDEX_ALLOCATION) {
             let alloc_data = self.read_attribute_data(index_alloc)?;
-            entries.extend(self.parse_index_allocation(&alloc_data)?);
+            entries.extend(index::parse_allocation_entries(&alloc_data, block_size));
         }
         
         Ok(entries)
@@ -281,7 +375,7 @@ impl NtfsFileSystem {
         let entry = self.mft.read_entry(mft_entry_num)?;
         
         // Find DATA attribute
-        let data_attr = entry.get_attribute(attributes::ATTR_TYPE_DATA)
+        let data_attr = entry.data_stream("")
             .ok_or("No data attribute")?;
         
         self.read_attribute_data(data_attr)
@@ -299,12 +393,20 @@ impl NtfsFileSystem {
     }
     
     fn read_non_resident_data(&mut self, non_res: &attributes::NonResidentAttribute) -> Result<Vec<u8>, &'static str> {
+        if non_res.compression_unit != 0 {
+            return self.read_compressed_data(non_res);
+        }
+        
         let mut data = Vec::with_capacity(non_res.real_size as usize);
         
         // Read data runs
         for run in &non_res.data_runs {
             let start_cluster = run.start_lcn;
             let cluster_count = run.length;
+            if run.sparse {
+                data.resize(data.len() + (cluster_count * self.cluster_size as u64) as usize, 0);
+                continue;
+            }
             
             // Read clusters
             for i in 0..cluster_count {
@@ -318,6 +420,149 @@ impl NtfsFileSystem {
         Ok(data)
     }
     
+    // A compressed stream is read a compression unit at a time
+    fn read_compressed_data(&mut self, non_res: &attributes::NonResidentAttribute) -> Result<Vec<u8>, &'static str> {
+        let unit_clusters = 1usize << non_res.compression_unit;
+        let lcns = attributes::runs_to_lcns(&non_res.data_runs);
+        let mut data = Vec::with_capacity(non_res.real_size as usize);
+        
+        for unit in lcns.chunks(unit_clusters) {
+            data.extend_from_slice(&self.read_compression_unit(unit, unit_clusters)?);
+        }
+        
+        data.resize(non_res.real_size as usize, 0);
+        Ok(data)
+    }
+    
+    // One compression unit's data, from the LCNs of its clusters. A unit
+    // with no clusters is zeros, one with all its clusters is stored as it
+    // is, and one with fewer holds LZNT1 chunks followed by a sparse tail.
+    fn read_compression_unit(&mut self, lcns: &[Option<u64>], unit_clusters: usize) -> Result<Vec<u8>, &'static str> {
+        let unit_size = unit_clusters * self.cluster_size as usize;
+        let stored: Vec<u64> = lcns.iter().filter_map(|lcn| *lcn).collect();
+        if stored.is_empty() {
+            return Ok(vec![0u8; unit_size]);
+        }
+        
+        let mut raw = Vec::with_capacity(stored.len() * self.cluster_size as usize);
+        for lcn in &stored {
+            raw.extend_from_slice(&self.read_cluster(*lcn)?);
+        }
+        
+        if stored.len() == unit_clusters {
+            Ok(raw)
+        } else {
+            lznt1::decompress(&raw, unit_size)
+        }
+    }
+    
+    // Read part of a file into `buffer`, returning how many bytes that was.
+    // Only the clusters under the range are read: whole sectors go straight
+    // into the buffer, and a compressed file is decompressed a unit at a
+    // time.
+    pub fn read_at_impl(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let mut map = self.open_stream(path)?;
+        let result = self.read_mapped(&mut map, offset, buffer);
+        self.keep_stream(path, map);
+        result
+    }
+    
+    pub fn file_size_impl(&mut self, path: &str) -> Result<u64, &'static str> {
+        let map = self.open_stream(path)?;
+        let size = map.size();
+        self.keep_stream(path, map);
+        Ok(size)
+    }
+    
+    // A file's stream map, taken out of those kept or made from its entry
+    fn open_stream(&mut self, path: &str) -> Result<StreamMap, &'static str> {
+        if let Some(map) = self.open_streams.remove(path) {
+            return Ok(map);
+        }
+        let record = self.find_file_entry(path)?;
+        let entry = self.mft.read_entry(record)?;
+        Ok(entry.data_stream("").map(StreamMap::new).unwrap_or(StreamMap::Resident(Vec::new())))
+    }
+    
+    fn keep_stream(&mut self, path: &str, map: StreamMap) {
+        if self.open_streams.len() >= OPEN_STREAMS {
+            self.open_streams.pop_first();
+        }
+        self.open_streams.insert(String::from(path), map);
+    }
+    
+    // Forget the stream maps kept, once a write may have moved a file's
+    // data or changed which file a path names
+    pub fn forget_streams(&mut self) {
+        self.open_streams.clear();
+    }
+    
+    fn read_mapped(&mut self, map: &mut StreamMap, offset: u64, buffer: &mut [u8]) -> Result<usize, &'static str> {
+        let (size, initialized_size, compression_unit, extents, last_unit) = match map {
+            StreamMap::Resident(data) => return Ok(super::copy_at(data, offset, buffer)),
+            StreamMap::NonResident { size, initialized_size, compression_unit, extents, last_unit } => {
+                (*size, *initialized_size, *compression_unit, extents, last_unit)
+            }
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let length = (size - offset).min(buffer.len() as u64) as usize;
+        let cluster_size = self.cluster_size as u64;
+        let unit_clusters = 1u64 << compression_unit;
+        
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let vcn = position / cluster_size;
+            
+            if compression_unit != 0 {
+                let unit = vcn / unit_clusters;
+                if last_unit.as_ref().map(|(number, _)| *number) != Some(unit) {
+                    let lcns: Vec<Option<u64>> = (unit * unit_clusters..(unit + 1) * unit_clusters)
+                        .map(|vcn| lookup_vcn(extents, vcn).and_then(|(lcn, _)| lcn))
+                        .collect();
+                    *last_unit = Some((unit, self.read_compression_unit(&lcns, unit_clusters as usize)?));
+                }
+                let data = &last_unit.as_ref().unwrap().1;
+                let within = (position - unit * unit_clusters * cluster_size) as usize;
+                let count = (data.len() - within).min(length - done);
+                buffer[done..done + count].copy_from_slice(&data[within..within + count]);
+                done += count;
+                continue;
+            }
+            
+            // As much as the extent holds contiguously from here
+            let (lcn, clusters) = lookup_vcn(extents, vcn).unwrap_or((None, 1));
+            let within = position % cluster_size;
+            let mut count = (clusters * cluster_size - within).min((length - done) as u64) as usize;
+            match lcn {
+                None => buffer[done..done + count].fill(0),
+                Some(lcn) => {
+                    let start = lcn * cluster_size + within;
+                    if start % SECTOR_SIZE as u64 == 0 && count % SECTOR_SIZE == 0 {
+                        let out = &mut buffer[done..done + count];
+                        self.disk.read_sectors(start / SECTOR_SIZE as u64, (count / SECTOR_SIZE) as u32, out)
+                            .map_err(|_| "Failed to read cluster")?;
+                    } else {
+                        // Not on sector boundaries, so a cluster at a time
+                        count = count.min((cluster_size - within) as usize);
+                        let cluster = self.read_cluster(lcn)?;
+                        let within = within as usize;
+                        buffer[done..done + count].copy_from_slice(&cluster[within..within + count]);
+                    }
+                }
+            }
+            done += count;
+        }
+        
+        // Past the initialized size the stream reads as zeros
+        if initialized_size < offset + length as u64 {
+            buffer[initialized_size.saturating_sub(offset) as usize..length].fill(0);
+        }
+        Ok(length)
+    }
+    
     fn read_cluster(&mut self, lcn: u64) -> Result<Vec<u8>, &'static str> {
         let mut data = vec![0u8; self.cluster_size as usize];
         let start_sector = lcn * self.boot_sector.sectors_per_cluster as u64;
@@ -331,16 +576,6 @@ impl NtfsFileSystem {
         Ok(data)
     }
     
-    fn parse_index_entries(&self, data: &[u8]) -> Result<Vec<DirectoryEntry>, &'static str> {
-        // Simplified index parsing
-        Ok(Vec::new())
-    }
-    
-    fn parse_index_allocation(&self, data: &[u8]) -> Result<Vec<DirectoryEntry>, &'static str> {
-        // Simplified index allocation parsing
-        Ok(Vec::new())
-    }
-    
     pub fn list_directory(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, &'static str> {
         // Navigate to directory
         let components: Vec<&str> = path.split('\\').filter(|s| !s.is_empty()).collect();
@@ -389,7 +624,7 @@ impl NtfsFileSystem {
     }
     
     fn get_file_size(&self, entry: &mft::MftEntry) -> Result<u64, &'static str> {
-        if let Some(data_attr) = entry.get_attribute(attributes::ATTR_TYPE_DATA) {
+        if let Some(data_attr) = entry.data_stream("") {
             match &data_attr.content {
                 attributes::AttributeContent::Resident(data) => Ok(data.len() as u64),
                 attributes::AttributeContent::NonResident(non_res) => Ok(non_res.real_size),
@@ -420,15 +655,30 @@ pub struct FileInfo {
 }
 
 // VFS Integration
-use super::{FileSystem, FileSystemError, FileType, FileInfo as VfsFileInfo};
+use super::{FileSystem, FileSystemError, FileType, FileInfo as VfsFileInfo, FsControl, FsControlReply};
+use super::StreamInfo;
 
 impl FileSystem for NtfsFileSystem {
+    fn fs_type(&self) -> &'static str {
+        "ntfs"
+    }
+
     fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
         // Create a temporary mutable clone for read operations
         // In production, this would use interior mutability or refactor the trait
         Err(FileSystemError::NotSupported)
     }
     
+    fn read_at(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        self.read_at_impl(path, offset, buffer)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn file_size(&mut self, path: &str) -> Result<u64, FileSystemError> {
+        self.file_size_impl(path)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
     fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
         self.write_file_impl(path, data)
             .map_err(|e| FileSystemError::IoError(String::from(e)))
@@ -455,4 +705,40 @@ impl FileSystem for NtfsFileSystem {
         // For now, return not supported
         Err(FileSystemError::NotSupported)
     }
+    
+    fn get_security(&mut self, path: &str) -> Result<Option<Vec<u8>>, FileSystemError> {
+        self.get_security_impl(path)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn set_security(&mut self, path: &str, descriptor: &[u8]) -> Result<(), FileSystemError> {
+        self.set_security_impl(path, descriptor)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn fs_control(&mut self, path: &str, request: FsControl) -> Result<FsControlReply, FileSystemError> {
+        self.fs_control_impl(path, request)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn list_streams(&mut self, path: &str) -> Result<Vec<StreamInfo>, FileSystemError> {
+        let streams = self.list_streams_impl(path)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))?;
+        Ok(streams.into_iter().map(|(name, size)| StreamInfo { name, size }).collect())
+    }
+    
+    fn read_named_stream(&mut self, path: &str, stream: &str) -> Result<Vec<u8>, FileSystemError> {
+        self.read_stream_impl(path, stream)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn write_named_stream(&mut self, path: &str, stream: &str, data: &[u8]) -> Result<(), FileSystemError> {
+        self.write_stream_impl(path, stream, data)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
+    
+    fn delete_named_stream(&mut self, path: &str, stream: &str) -> Result<(), FileSystemError> {
+        self.delete_stream_impl(path, stream)
+            .map_err(|e| FileSystemError::IoError(String::from(e)))
+    }
 }
\ No newline at end of file
@@ -1,30 +1,292 @@
-// NTFS Security Descriptors
+// NTFS Security Descriptors ($Secure)
+//
+// NTFS stores each distinct security descriptor once, in the $SDS data stream
+// of the $Secure system file. Files refer to their descriptor by the security
+// ID kept in $STANDARD_INFORMATION. $SDS is written in 256KB blocks, each
+// followed by a mirror copy; entries never straddle a block. The $SII (by ID)
+// and $SDH (by hash) indexes are rebuilt in memory from $SDS at mount.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec::Vec;
+use super::{NtfsFileSystem, MFT_ENTRY_SECURE};
+use super::attributes::{Attribute, AttributeContent, ATTR_TYPE_DATA, ATTR_TYPE_SECURITY_DESCRIPTOR, ATTR_TYPE_STANDARD_INFO};
 
-// Security Descriptor
-pub struct SecurityDescriptor {
-    pub revision: u8,
-    pub control: u16,
-    pub owner_sid: Vec<u8>,
-    pub group_sid: Vec<u8>,
-    pub sacl: Option<Vec<u8>>,
-    pub dacl: Option<Vec<u8>>,
+pub const SDS_STREAM_NAME: &str = "$SDS";
+const SDS_BLOCK_SIZE: u64 = 0x40000;
+const SDS_ENTRY_HEADER_SIZE: usize = 20;
+const FIRST_SECURITY_ID: u32 = 0x100;
+
+// Security ID field in $STANDARD_INFORMATION
+const STANDARD_INFO_SECURITY_ID_OFFSET: usize = 52;
+
+// $SDS entry header
+#[derive(Debug, Clone, Copy)]
+pub struct SdsEntry {
+    pub hash: u32,
+    pub security_id: u32,
+    pub offset: u64,
+    pub length: u32,
+}
+
+// In-memory view of $Secure
+pub struct SecureStream {
+    sds: Vec<u8>,
+    sii: BTreeMap<u32, SdsEntry>,
+    sdh: BTreeMap<u32, Vec<u32>>,
+    next_id: u32,
+    dirty: bool,
 }
 
-impl SecurityDescriptor {
-    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
-        if data.len() < 20 {
-            return Err("Security descriptor too small");
-        }
-        
-        // Simplified parsing
-        Ok(Self {
-            revision: data[0],
-            control: u16::from_le_bytes([data[2], data[3]]),
-            owner_sid: Vec::new(),
-            group_sid: Vec::new(),
-            sacl: None,
-            dacl: None,
-        })
-    }
-}
\ No newline at end of file
+/// The $SDH hash: each little-endian dword is added to the running hash
+/// rotated left by 3
+pub fn descriptor_hash(descriptor: &[u8]) -> u32 {
+    descriptor.chunks_exact(4).fold(0u32, |hash, word| {
+        u32::from_le_bytes([word[0], word[1], word[2], word[3]]).wrapping_add(hash.rotate_left(3))
+    })
+}
+
+impl SecureStream {
+    pub fn new() -> Self {
+        Self {
+            sds: Vec::new(),
+            sii: BTreeMap::new(),
+            sdh: BTreeMap::new(),
+            next_id: FIRST_SECURITY_ID,
+            dirty: false,
+        }
+    }
+
+    /// Rebuild the indexes from the primary copy of each $SDS block
+    pub fn parse(sds: &[u8]) -> Self {
+        let mut stream = Self::new();
+        stream.sds = sds.to_vec();
+
+        let mut block = 0u64;
+        while block < sds.len() as u64 {
+            let block_end = (block + SDS_BLOCK_SIZE).min(sds.len() as u64);
+            let mut offset = block;
+            while offset + SDS_ENTRY_HEADER_SIZE as u64 <= block_end {
+                let header = &sds[offset as usize..offset as usize + SDS_ENTRY_HEADER_SIZE];
+                let entry = SdsEntry {
+                    hash: u32::from_le_bytes([header[0], header[1], header[2], header[3]]),
+                    security_id: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+                    offset: u64::from_le_bytes([
+                        header[8], header[9], header[10], header[11],
+                        header[12], header[13], header[14], header[15],
+                    ]),
+                    length: u32::from_le_bytes([header[16], header[17], header[18], header[19]]),
+                };
+                if entry.length as usize <= SDS_ENTRY_HEADER_SIZE
+                    || entry.offset != offset
+                    || offset + entry.length as u64 > block_end
+                {
+                    break;
+                }
+
+                stream.sii.insert(entry.security_id, entry);
+                stream.sdh.entry(entry.hash).or_default().push(entry.security_id);
+                stream.next_id = stream.next_id.max(entry.security_id + 1);
+                offset = align16(offset + entry.length as u64);
+            }
+            block += 2 * SDS_BLOCK_SIZE;
+        }
+
+        stream
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.sds
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Self-relative descriptor for a security ID
+    pub fn lookup(&self, security_id: u32) -> Option<&[u8]> {
+        let entry = self.sii.get(&security_id)?;
+        let start = entry.offset as usize + SDS_ENTRY_HEADER_SIZE;
+        self.sds.get(start..entry.offset as usize + entry.length as usize)
+    }
+
+    /// Security ID for a descriptor, appending it to $SDS if it is new
+    pub fn insert(&mut self, descriptor: &[u8]) -> u32 {
+        let hash = descriptor_hash(descriptor);
+        if let Some(ids) = self.sdh.get(&hash) {
+            for &id in ids {
+                if self.lookup(id) == Some(descriptor) {
+                    return id;
+                }
+            }
+        }
+
+        let length = (SDS_ENTRY_HEADER_SIZE + descriptor.len()) as u64;
+        let mut offset = self.append_offset();
+        if offset % (2 * SDS_BLOCK_SIZE) + length > SDS_BLOCK_SIZE {
+            // Does not fit in this block: start the next primary block
+            offset = (offset / (2 * SDS_BLOCK_SIZE) + 1) * 2 * SDS_BLOCK_SIZE;
+        }
+
+        let security_id = self.next_id;
+        self.next_id += 1;
+        let entry = SdsEntry { hash, security_id, offset, length: length as u32 };
+
+        let mut record = Vec::with_capacity(length as usize);
+        record.extend_from_slice(&entry.hash.to_le_bytes());
+        record.extend_from_slice(&entry.security_id.to_le_bytes());
+        record.extend_from_slice(&entry.offset.to_le_bytes());
+        record.extend_from_slice(&entry.length.to_le_bytes());
+        record.extend_from_slice(descriptor);
+
+        // Primary copy, then the mirror one block further on
+        self.write_at(offset, &record);
+        self.write_at(offset + SDS_BLOCK_SIZE, &record);
+
+        self.sii.insert(security_id, entry);
+        self.sdh.entry(hash).or_default().push(security_id);
+        self.dirty = true;
+        security_id
+    }
+
+    fn append_offset(&self) -> u64 {
+        self.sii
+            .values()
+            .map(|entry| align16(entry.offset + entry.length as u64))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) {
+        let end = offset as usize + data.len();
+        if self.sds.len() < end {
+            self.sds.resize(align16(end as u64) as usize, 0);
+        }
+        self.sds[offset as usize..end].copy_from_slice(data);
+    }
+}
+
+fn align16(value: u64) -> u64 {
+    (value + 15) & !15
+}
+
+impl NtfsFileSystem {
+    // Load $Secure:$SDS into the in-memory security cache
+    pub fn load_secure_stream(&mut self) -> Result<(), &'static str> {
+        let entry = self.mft.read_entry(MFT_ENTRY_SECURE)?;
+        let attr = entry
+            .attributes
+            .iter()
+            .find(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name == SDS_STREAM_NAME)
+            .ok_or("$Secure has no $SDS stream")?
+            .clone();
+        let sds = self.read_attribute_data(&attr)?;
+        *self.secure.lock() = SecureStream::parse(&sds);
+        Ok(())
+    }
+
+    // Write $SDS back if descriptors were added since the last flush
+    fn flush_secure_stream(&mut self) -> Result<(), &'static str> {
+        let sds = {
+            let secure = self.secure.lock();
+            if !secure.is_dirty() {
+                return Ok(());
+            }
+            secure.data().to_vec()
+        };
+
+        let mut entry = self.mft.read_entry(MFT_ENTRY_SECURE)?;
+        let clusters_needed = (sds.len() as u64 + self.cluster_size as u64 - 1) / self.cluster_size as u64;
+        let position = entry
+            .attributes
+            .iter()
+            .position(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name == SDS_STREAM_NAME);
+
+        // Rewrite in place when the current run is large enough, otherwise
+        // move the stream to a fresh run
+        let existing_run = position.and_then(|i| match &entry.attributes[i].content {
+            AttributeContent::NonResident(non_res) if non_res.data_runs.len() == 1 => {
+                Some((non_res.data_runs[0].start_lcn, non_res.data_runs[0].length))
+            }
+            _ => None,
+        });
+        let clusters: Vec<u64> = match existing_run {
+            Some((start, length)) if length >= clusters_needed => (start..start + length).collect(),
+            _ => {
+                let clusters = self.allocate_clusters(clusters_needed)?;
+                if let Some((start, length)) = existing_run {
+                    let old: Vec<u64> = (start..start + length).collect();
+                    self.deallocate_clusters(&old)?;
+                }
+                clusters
+            }
+        };
+        self.write_clusters(&clusters, &sds)?;
+
+        let mut attr = self.create_non_resident_attribute(&clusters, sds.len());
+        attr.name = String::from(SDS_STREAM_NAME);
+        match position {
+            Some(i) => entry.attributes[i] = attr,
+            None => entry.attributes.push(attr),
+        }
+        self.mft.write_entry(&mut *self.disk, MFT_ENTRY_SECURE, &entry)?;
+
+        self.secure.lock().mark_clean();
+        Ok(())
+    }
+
+    // Self-relative descriptor of a file, or None if it has none
+    pub fn get_security_impl(&mut self, path: &str) -> Result<Option<Vec<u8>>, &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        let entry = self.mft.read_entry(entry_num)?;
+
+        let security_id = entry
+            .get_attribute(ATTR_TYPE_STANDARD_INFO)
+            .and_then(|attr| match &attr.content {
+                AttributeContent::Resident(data) => data.get(
+                    STANDARD_INFO_SECURITY_ID_OFFSET..STANDARD_INFO_SECURITY_ID_OFFSET + 4,
+                ),
+                _ => None,
+            })
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .unwrap_or(0);
+        if security_id != 0 {
+            return Ok(self.secure.lock().lookup(security_id).map(|sd| sd.to_vec()));
+        }
+
+        // Volumes formatted before NTFS 3.0 keep a per-file attribute instead
+        match entry.get_attribute(ATTR_TYPE_SECURITY_DESCRIPTOR).cloned() {
+            Some(attr) => self.read_attribute_data(&attr).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Store a descriptor in $Secure and point the file at it
+    pub fn set_security_impl(&mut self, path: &str, descriptor: &[u8]) -> Result<(), &'static str> {
+        let entry_num = self.find_entry_by_path(path)?;
+        let mut entry = self.mft.read_entry(entry_num)?;
+
+        let security_id = self.secure.lock().insert(descriptor);
+        self.flush_secure_stream()?;
+
+        let std_info = entry
+            .attributes
+            .iter_mut()
+            .find(|attr| attr.type_code == ATTR_TYPE_STANDARD_INFO)
+            .ok_or("No standard information attribute")?;
+        match &mut std_info.content {
+            AttributeContent::Resident(data) if data.len() >= STANDARD_INFO_SECURITY_ID_OFFSET + 4 => {
+                data[STANDARD_INFO_SECURITY_ID_OFFSET..STANDARD_INFO_SECURITY_ID_OFFSET + 4]
+                    .copy_from_slice(&security_id.to_le_bytes());
+            }
+            _ => return Err("Standard information predates NTFS 3.0"),
+        }
+        // The ID supersedes any legacy per-file descriptor
+        entry.attributes.retain(|attr: &Attribute| attr.type_code != ATTR_TYPE_SECURITY_DESCRIPTOR);
+
+        self.mft.write_entry(&mut *self.disk, entry_num, &entry)
+    }
+}
@@ -1,13 +1,15 @@
 // NTFS Write Operations Implementation
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
-use super::{NtfsFileSystem, MFT_ENTRY_ROOT, DirectoryEntry};
+use super::{NtfsFileSystem, MFT_ENTRY_ROOT, DirectoryEntry, lznt1};
 use super::mft::{MftEntry, MFT_ENTRY_IS_DIRECTORY};
 use super::attributes::{
-    self, Attribute, AttributeContent, NonResidentAttribute, DataRun,
+    self, Attribute, AttributeContent, NonResidentAttribute,
     ATTR_TYPE_DATA, ATTR_TYPE_INDEX_ROOT, ATTR_TYPE_STANDARD_INFO,
     create_standard_info_attribute, create_file_name_attribute,
-    create_data_attribute, update_attribute_data,
+    runs_to_lcns, lcns_to_runs,
+    ATTR_FLAG_COMPRESSED, ATTR_FLAG_SPARSE, COMPRESSION_UNIT,
 };
 use crate::drivers::disk::DiskDriver;
 
@@ -58,12 +60,16 @@ impl NtfsFileSystem {
         // Get current timestamp
         let timestamp = Self::get_current_timestamp();
         
+        // Files created in a compressed directory are compressed
+        let compressed = self.mft.read_entry(parent_entry)?.file_attributes & 0x800 != 0;
+        let file_attributes = if compressed { 0x800 } else { 0x80 };
+        
         // Add standard information attribute
         let std_info = create_standard_info_attribute(
             timestamp,
             timestamp,
             timestamp,
-            0x80, // Normal file
+            file_attributes,
         );
         entry.attributes.push(std_info);
         
@@ -77,30 +83,16 @@ impl NtfsFileSystem {
         
         // Add data attribute
         if data.len() > 0 {
-            // Determine if data should be resident or non-resident
-            if data.len() <= 700 {
-                // Resident data
-                let data_attr = create_data_attribute(None, data.to_vec());
-                entry.attributes.push(data_attr);
-            } else {
-                // Non-resident data - allocate clusters
-                let clusters_needed = (data.len() + self.cluster_size as usize - 1) / self.cluster_size as usize;
-                let allocated_clusters = self.allocate_clusters(clusters_needed as u64)?;
-                
-                // Write data to clusters
-                self.write_clusters(&allocated_clusters, data)?;
-                
-                // Create non-resident data attribute
-                let data_attr = self.create_non_resident_attribute(&allocated_clusters, data.len());
-                entry.attributes.push(data_attr);
-            }
+            let flags = if compressed { ATTR_FLAG_COMPRESSED } else { 0 };
+            let data_attr = self.data_attribute(data, flags)?;
+            entry.attributes.push(data_attr);
         }
         
         // Update entry metadata
         entry.created_time = timestamp;
         entry.modified_time = timestamp;
         entry.accessed_time = timestamp;
-        entry.file_attributes = 0x80;
+        entry.file_attributes = file_attributes;
         
         // Write MFT entry
         self.mft.write_entry(&mut *self.disk, entry_num, &entry)?;
@@ -120,6 +112,8 @@ impl NtfsFileSystem {
     
     // Update existing file data
     pub fn update_file_data(&mut self, entry_num: u64, new_data: &[u8]) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Begin transaction
         let transaction_id = if let Some(ref journal) = self.journal {
             Some(journal.begin_transaction())
@@ -130,48 +124,23 @@ impl NtfsFileSystem {
         // Read existing entry
         let mut entry = self.mft.read_entry(entry_num)?;
         
-        // Find and update data attribute
-        let mut found = false;
-        for attr in &mut entry.attributes {
-            if attr.type_code == ATTR_TYPE_DATA {
-                // Update the data
-                update_attribute_data(attr, new_data.to_vec())?;
-                
-                // Handle cluster allocation for non-resident data
-                if let AttributeContent::NonResident(ref mut non_res) = attr.content {
-                    let clusters_needed = (new_data.len() + self.cluster_size as usize - 1) / self.cluster_size as usize;
-                    
-                    // Deallocate old clusters if shrinking
-                    if clusters_needed < non_res.data_runs[0].length as usize {
-                        let clusters_to_free: Vec<u64> = ((clusters_needed as u64)..non_res.data_runs[0].length)
-                            .map(|i| non_res.data_runs[0].start_lcn + i)
-                            .collect();
-                        self.deallocate_clusters(&clusters_to_free)?;
-                    }
-                    // Allocate new clusters if growing
-                    else if clusters_needed > non_res.data_runs[0].length as usize {
-                        let additional = clusters_needed as u64 - non_res.data_runs[0].length;
-                        let new_clusters = self.allocate_clusters(additional)?;
-                        // Update data runs
-                        // This is simplified - real implementation would handle complex runs
-                    }
-                    
-                    // Write data to clusters
-                    let clusters: Vec<u64> = (0..clusters_needed as u64)
-                        .map(|i| non_res.data_runs[0].start_lcn + i)
-                        .collect();
-                    self.write_clusters(&clusters, new_data)?;
+        // Find and update data attribute, keeping its compressed and
+        // sparse flags
+        let position = entry.attributes.iter()
+            .position(|attr| attr.type_code == ATTR_TYPE_DATA && attr.name.is_empty());
+        match position {
+            Some(index) => {
+                if let AttributeContent::NonResident(ref non_res) = entry.attributes[index].content {
+                    self.free_runs(non_res)?;
                 }
-                
-                found = true;
-                break;
+                let flags = entry.attributes[index].flags;
+                entry.attributes[index].content = self.data_attribute(new_data, flags)?.content;
+            }
+            None => {
+                // No data attribute, create one
+                let data_attr = self.data_attribute(new_data, 0)?;
+                entry.attributes.push(data_attr);
             }
-        }
-        
-        if !found {
-            // No data attribute, create one
-            let data_attr = create_data_attribute(None, new_data.to_vec());
-            entry.attributes.push(data_attr);
         }
         
         // Update timestamps
@@ -262,6 +231,8 @@ impl NtfsFileSystem {
     
     // Delete a file or directory
     pub fn delete_file_impl(&mut self, path: &str) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Parse path
         let components: Vec<&str> = path.split('\\').filter(|s| !s.is_empty()).collect();
         if components.is_empty() {
@@ -298,7 +269,7 @@ impl NtfsFileSystem {
                 if let AttributeContent::NonResident(ref non_res) = attr.content {
                     // Deallocate all data clusters
                     let mut clusters_to_free = Vec::new();
-                    for run in &non_res.data_runs {
+                    for run in non_res.data_runs.iter().filter(|run| !run.sparse) {
                         for i in 0..run.length {
                             clusters_to_free.push(run.start_lcn + i);
                         }
@@ -319,6 +290,8 @@ impl NtfsFileSystem {
     
     // Rename a file or directory
     pub fn rename_file(&mut self, old_path: &str, new_name: &str) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Parse old path
         let components: Vec<&str> = old_path.split('\\').filter(|s| !s.is_empty()).collect();
         if components.is_empty() {
@@ -417,10 +390,13 @@ impl NtfsFileSystem {
         
         for (i, cluster) in clusters.iter().enumerate() {
             let offset = i * cluster_size;
-            let end = core::cmp::min(offset + cluster_size, data.len());
             
+            // Clusters past the end of the data are written as zeros
             let mut cluster_data = vec![0u8; cluster_size];
-            cluster_data[..end - offset].copy_from_slice(&data[offset..end]);
+            if offset < data.len() {
+                let end = core::cmp::min(offset + cluster_size, data.len());
+                cluster_data[..end - offset].copy_from_slice(&data[offset..end]);
+            }
             
             let sector = cluster * sectors_per_cluster as u64;
             self.disk.write_sectors(sector, sectors_per_cluster, &cluster_data)
@@ -430,19 +406,82 @@ impl NtfsFileSystem {
         Ok(())
     }
     
+    // Free the clusters behind a stream's runs
+    pub fn free_runs(&mut self, non_res: &NonResidentAttribute) -> Result<(), &'static str> {
+        let clusters: Vec<u64> = runs_to_lcns(&non_res.data_runs).into_iter().flatten().collect();
+        self.deallocate_clusters(&clusters)
+    }
+    
+    // A data attribute holding `data`, resident if it is small and otherwise
+    // written out as `flags` ask
+    pub fn data_attribute(&mut self, data: &[u8], flags: u16) -> Result<Attribute, &'static str> {
+        let content = if data.len() <= 700 {
+            AttributeContent::Resident(data.to_vec())
+        } else {
+            AttributeContent::NonResident(self.write_stream(data, flags)?)
+        };
+        
+        Ok(Attribute {
+            type_code: ATTR_TYPE_DATA,
+            name: String::new(),
+            flags,
+            content,
+        })
+    }
+    
+    // Write a stream to newly allocated clusters, in the form its attribute
+    // flags ask for. Compressed data goes a compression unit at a time: a
+    // unit of zeros is left sparse, one LZNT1 shrinks by a cluster or more
+    // is stored compressed with a sparse tail, and any other is stored as
+    // it is. Sparse data leaves out the clusters that are all zeros.
+    pub fn write_stream(&mut self, data: &[u8], flags: u16) -> Result<NonResidentAttribute, &'static str> {
+        let cluster_size = self.cluster_size as usize;
+        let compressed = flags & ATTR_FLAG_COMPRESSED != 0;
+        let holes = flags & (ATTR_FLAG_COMPRESSED | ATTR_FLAG_SPARSE) != 0;
+        let unit_clusters = if compressed { 1usize << COMPRESSION_UNIT } else { 1 };
+        let mut lcns: Vec<Option<u64>> = Vec::new();
+        
+        for unit in data.chunks(unit_clusters * cluster_size) {
+            // A compression unit always spans its full VCN range
+            let used = (unit.len() + cluster_size - 1) / cluster_size;
+            let vcns = if compressed { unit_clusters } else { used };
+            if holes && unit.iter().all(|&b| b == 0) {
+                lcns.resize(lcns.len() + vcns, None);
+                continue;
+            }
+            
+            let packed = if compressed { lznt1::compress(unit) } else { Vec::new() };
+            let packed_clusters = (packed.len() + cluster_size - 1) / cluster_size;
+            let (stored, count) = if compressed && packed_clusters < unit_clusters {
+                (&packed[..], packed_clusters)
+            } else {
+                (unit, vcns)
+            };
+            
+            let clusters = self.allocate_clusters(count as u64)?;
+            self.write_clusters(&clusters, stored)?;
+            lcns.extend(clusters.iter().map(|&lcn| Some(lcn)));
+            lcns.resize(lcns.len() + vcns - count, None);
+        }
+        
+        let stored = lcns.iter().filter(|lcn| lcn.is_some()).count();
+        Ok(NonResidentAttribute {
+            start_vcn: 0,
+            last_vcn: (lcns.len() as u64).saturating_sub(1),
+            allocated_size: (lcns.len() * cluster_size) as u64,
+            real_size: data.len() as u64,
+            initialized_size: data.len() as u64,
+            compression_unit: if compressed { COMPRESSION_UNIT } else { 0 },
+            compressed_size: (stored * cluster_size) as u64,
+            data_runs: lcns_to_runs(&lcns),
+        })
+    }
+    
     // Create non-resident attribute for large data
     pub fn create_non_resident_attribute(&self, clusters: &[u64], data_size: usize) -> Attribute {
         // Create data runs from allocated clusters
-        let mut data_runs = Vec::new();
-        
-        if !clusters.is_empty() {
-            // Simplified: assume contiguous clusters
-            // Real implementation would handle fragmentation
-            data_runs.push(DataRun {
-                length: clusters.len() as u64,
-                start_lcn: clusters[0],
-            });
-        }
+        let lcns: Vec<Option<u64>> = clusters.iter().map(|&lcn| Some(lcn)).collect();
+        let data_runs = lcns_to_runs(&lcns);
         
         let non_res = NonResidentAttribute {
             start_vcn: 0,
@@ -450,6 +489,8 @@ impl NtfsFileSystem {
             allocated_size: (clusters.len() * self.cluster_size as usize) as u64,
             real_size: data_size as u64,
             initialized_size: data_size as u64,
+            compression_unit: 0,
+            compressed_size: 0,
             data_runs,
         };
         
@@ -529,11 +570,9 @@ impl NtfsFileSystem {
         Ok(())
     }
     
-    // Get current Windows timestamp
+    // Current Windows timestamp: 100-nanosecond intervals since 1601-01-01
     pub fn get_current_timestamp() -> u64 {
-        // This is a placeholder - real implementation would get system time
-        // Windows timestamps are 100-nanosecond intervals since 1601-01-01
-        0x01D7C4F0A0000000u64
+        crate::time::filetime_now()
     }
     
     // Set file attributes
@@ -565,6 +604,8 @@ impl NtfsFileSystem {
     
     // Truncate file to specified size
     pub fn truncate_file(&mut self, path: &str, new_size: u64) -> Result<(), &'static str> {
+        self.forget_streams();
+        
         // Find file
         let entry_num = self.find_entry_by_path(path)?;
         
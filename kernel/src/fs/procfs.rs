@@ -0,0 +1,111 @@
+//! Kernel state as files, mounted on /proc
+//!
+//! Subsystems `register` a path and a function that renders the file; the
+//! text is produced afresh on every read. Directories are whatever the
+//! registered paths imply. Everything is read-only.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{FileInfo, FileSystem, FileSystemError, FileType};
+
+static FILES: Mutex<Vec<(&'static str, fn() -> String)>> = Mutex::new(Vec::new());
+
+/// Serve `path` (relative to /proc, like `numa/topology`) from `generator`
+pub fn register(path: &'static str, generator: fn() -> String) {
+    let mut files = FILES.lock();
+    files.retain(|(existing, _)| *existing != path);
+    files.push((path, generator));
+}
+
+pub struct ProcFs;
+
+enum Node {
+    // Entries, and whether each is a directory
+    Directory(Vec<(String, bool)>),
+    File(fn() -> String),
+}
+
+fn lookup(path: &str) -> Result<Node, FileSystemError> {
+    let path = path.trim_matches('/');
+    let files = FILES.lock();
+    if let Some(&(_, generator)) = files.iter().find(|(file, _)| *file == path) {
+        return Ok(Node::File(generator));
+    }
+
+    let prefix = if path.is_empty() { String::new() } else { String::from(path) + "/" };
+    let mut entries: Vec<(String, bool)> = Vec::new();
+    for (file, _) in files.iter() {
+        let Some(rest) = file.strip_prefix(prefix.as_str()) else {
+            continue;
+        };
+        let entry = match rest.split_once('/') {
+            Some((directory, _)) => (directory.to_string(), true),
+            None => (rest.to_string(), false),
+        };
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+    if entries.is_empty() && !path.is_empty() {
+        return Err(FileSystemError::NotFound);
+    }
+    entries.sort();
+    Ok(Node::Directory(entries))
+}
+
+impl FileSystem for ProcFs {
+    fn fs_type(&self) -> &'static str {
+        "proc"
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        match lookup(path)? {
+            Node::File(generator) => Ok(generator().into_bytes()),
+            Node::Directory(_) => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn write_file(&mut self, _path: &str, _data: &[u8]) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn create_directory(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        match lookup(path)? {
+            // Files have no size until they are read, as on Linux
+            Node::Directory(entries) => Ok(entries
+                .into_iter()
+                .map(|(name, directory)| {
+                    let (file_type, permissions) =
+                        if directory { (FileType::Directory, 0o555) } else { (FileType::Regular, 0o444) };
+                    FileInfo { name, size: 0, file_type, permissions, modified: 0 }
+                })
+                .collect()),
+            Node::File(_) => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn delete(&mut self, _path: &str) -> Result<(), FileSystemError> {
+        Err(FileSystemError::PermissionDenied)
+    }
+
+    fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError> {
+        let name = path.trim_end_matches('/').rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("/");
+        let (file_type, permissions) = match lookup(path)? {
+            Node::Directory(_) => (FileType::Directory, 0o555),
+            Node::File(_) => (FileType::Regular, 0o444),
+        };
+        Ok(FileInfo { name: name.to_string(), size: 0, file_type, permissions, modified: 0 })
+    }
+}
+
+/// Mount /proc; files can be registered before or after
+pub fn init() {
+    super::vfs::VFS.lock().mount(String::from("/proc"), Box::new(ProcFs));
+}
@@ -0,0 +1,297 @@
+// In-memory file system, the root while the initrd is in use
+use super::{FileSystem, FileSystemError, FileInfo, FileType, FsStats};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// Symbolic links followed before a lookup gives up, as with ELOOP
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+const DEFAULT_FILE_MODE: u32 = 0o644;
+const DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+// The unit `statfs` counts in
+const BLOCK_SIZE: u64 = 1024;
+
+#[derive(Debug, Clone)]
+enum NodeKind {
+    File(Vec<u8>),
+    Directory,
+    SymLink(String),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    kind: NodeKind,
+    permissions: u32,
+    modified: u64,
+}
+
+// Paths are kept without leading or trailing slashes; the root is ""
+fn normalize(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        if component == ".." {
+            components.pop();
+        } else {
+            components.push(component);
+        }
+    }
+    components.join("/")
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rfind('/').map_or("", |pos| &path[..pos])
+}
+
+fn name_of(path: &str) -> &str {
+    path.rfind('/').map_or(path, |pos| &path[pos + 1..])
+}
+
+fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+fn now() -> u64 {
+    crate::time::realtime_ns() / 1_000_000_000
+}
+
+pub struct RamFs {
+    nodes: BTreeMap<String, Node>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::new(), Node { kind: NodeKind::Directory, permissions: DEFAULT_DIRECTORY_MODE, modified: 0 });
+        Self { nodes }
+    }
+
+    /// Number of files, directories and links, the root included
+    pub fn entry_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Total size of the files' contents
+    pub fn size(&self) -> u64 {
+        self.nodes
+            .values()
+            .map(|node| match &node.kind {
+                NodeKind::File(data) => data.len() as u64,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    // Missing parents are created, as archives need not list them
+    fn insert(&mut self, path: &str, kind: NodeKind, permissions: u32, modified: u64) -> Result<(), FileSystemError> {
+        let path = normalize(path);
+        if path.is_empty() {
+            // An archive's "." entry describes the root
+            return match kind {
+                NodeKind::Directory => {
+                    if let Some(root) = self.nodes.get_mut("") {
+                        root.permissions = permissions;
+                        root.modified = modified;
+                    }
+                    Ok(())
+                }
+                _ => Err(FileSystemError::InvalidPath),
+            };
+        }
+        self.make_parents(&path)?;
+        self.nodes.insert(path, Node { kind, permissions, modified });
+        Ok(())
+    }
+
+    fn make_parents(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            let parent = &path[..end + pos];
+            match self.nodes.get(parent).map(|node| &node.kind) {
+                Some(NodeKind::Directory) => {}
+                Some(_) => return Err(FileSystemError::InvalidPath),
+                None => {
+                    self.nodes.insert(
+                        parent.to_string(),
+                        Node { kind: NodeKind::Directory, permissions: DEFAULT_DIRECTORY_MODE, modified: 0 },
+                    );
+                }
+            }
+            end += pos + 1;
+        }
+        Ok(())
+    }
+
+    pub fn insert_file(&mut self, path: &str, data: Vec<u8>, permissions: u32, modified: u64) -> Result<(), FileSystemError> {
+        self.insert(path, NodeKind::File(data), permissions, modified)
+    }
+
+    pub fn insert_directory(&mut self, path: &str, permissions: u32, modified: u64) -> Result<(), FileSystemError> {
+        self.insert(path, NodeKind::Directory, permissions, modified)
+    }
+
+    pub fn insert_symlink(&mut self, path: &str, target: &str, modified: u64) -> Result<(), FileSystemError> {
+        self.insert(path, NodeKind::SymLink(target.to_string()), 0o777, modified)
+    }
+
+    // Resolve symbolic links in every component; the last one is followed
+    // only if `follow` is set
+    fn resolve(&self, path: &str, follow: bool) -> Result<String, FileSystemError> {
+        let mut path = normalize(path);
+        let mut depth = 0;
+        'restart: loop {
+            let mut end = 0;
+            loop {
+                let next = path[end..].find('/').map(|pos| end + pos);
+                let prefix = &path[..next.unwrap_or(path.len())];
+                let last = next.is_none();
+                let node = self.nodes.get(prefix).ok_or(FileSystemError::NotFound)?;
+                match &node.kind {
+                    NodeKind::SymLink(target) if follow || !last => {
+                        depth += 1;
+                        if depth > MAX_SYMLINK_DEPTH {
+                            return Err(FileSystemError::InvalidPath);
+                        }
+                        let rest = next.map_or("", |pos| &path[pos..]);
+                        let base = if target.starts_with('/') {
+                            String::new()
+                        } else {
+                            parent_of(prefix).to_string() + "/"
+                        };
+                        path = normalize(&(base + target + rest));
+                        continue 'restart;
+                    }
+                    NodeKind::File(_) | NodeKind::SymLink(_) if !last => return Err(FileSystemError::InvalidPath),
+                    _ => {}
+                }
+                match next {
+                    Some(pos) => end = pos + 1,
+                    None => return Ok(path),
+                }
+            }
+        }
+    }
+
+    fn info(name: &str, node: &Node) -> FileInfo {
+        let (size, file_type) = match &node.kind {
+            NodeKind::File(data) => (data.len() as u64, FileType::Regular),
+            NodeKind::Directory => (0, FileType::Directory),
+            NodeKind::SymLink(target) => (target.len() as u64, FileType::SymLink),
+        };
+        FileInfo { name: name.to_string(), size, file_type, permissions: node.permissions, modified: node.modified }
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn fs_type(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        let path = self.resolve(path, true)?;
+        match &self.nodes[&path].kind {
+            NodeKind::File(data) => Ok(data.clone()),
+            _ => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
+        let path = match self.resolve(path, true) {
+            Ok(path) => path,
+            Err(FileSystemError::NotFound) => {
+                // A new file, in a directory that exists
+                let path = normalize(path);
+                let parent = self.resolve(parent_of(&path), true)?;
+                if path.is_empty() || !matches!(self.nodes[&parent].kind, NodeKind::Directory) {
+                    return Err(FileSystemError::InvalidPath);
+                }
+                let path = join(&parent, name_of(&path));
+                self.nodes.insert(
+                    path.clone(),
+                    Node { kind: NodeKind::File(Vec::new()), permissions: DEFAULT_FILE_MODE, modified: now() },
+                );
+                path
+            }
+            Err(error) => return Err(error),
+        };
+        let node = self.nodes.get_mut(&path).ok_or(FileSystemError::NotFound)?;
+        match &mut node.kind {
+            NodeKind::File(contents) => {
+                contents.clear();
+                contents.extend_from_slice(data);
+                node.modified = now();
+                Ok(())
+            }
+            _ => Err(FileSystemError::InvalidPath),
+        }
+    }
+
+    fn create_directory(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let path = normalize(path);
+        if self.nodes.contains_key(&path) {
+            return Err(FileSystemError::AlreadyExists);
+        }
+        let parent = self.resolve(parent_of(&path), true)?;
+        if !matches!(self.nodes[&parent].kind, NodeKind::Directory) {
+            return Err(FileSystemError::InvalidPath);
+        }
+        self.nodes.insert(
+            join(&parent, name_of(&path)),
+            Node { kind: NodeKind::Directory, permissions: DEFAULT_DIRECTORY_MODE, modified: now() },
+        );
+        Ok(())
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        let path = self.resolve(path, true)?;
+        if !matches!(self.nodes[&path].kind, NodeKind::Directory) {
+            return Err(FileSystemError::InvalidPath);
+        }
+        let prefix = if path.is_empty() { path } else { path + "/" };
+        Ok(self
+            .nodes
+            .range(prefix.clone()..)
+            .skip_while(|(name, _)| name.is_empty())
+            .take_while(|(name, _)| name.starts_with(prefix.as_str()))
+            .filter(|(name, _)| !name[prefix.len()..].contains('/'))
+            .map(|(name, node)| Self::info(&name[prefix.len()..], node))
+            .collect())
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let path = self.resolve(path, false)?;
+        if path.is_empty() {
+            return Err(FileSystemError::PermissionDenied);
+        }
+        let prefix = path.clone() + "/";
+        if self.nodes.range(prefix.clone()..).next().is_some_and(|(name, _)| name.starts_with(prefix.as_str())) {
+            return Err(FileSystemError::IoError(String::from("Directory not empty")));
+        }
+        self.nodes.remove(&path);
+        Ok(())
+    }
+
+    fn get_file_info(&self, path: &str) -> Result<FileInfo, FileSystemError> {
+        let path = self.resolve(path, false)?;
+        let name = if path.is_empty() { "/" } else { name_of(&path) };
+        Ok(Self::info(name, &self.nodes[&path]))
+    }
+
+    // Files live on the kernel heap, so the room left is the heap's
+    fn statfs(&self) -> Result<FsStats, FileSystemError> {
+        let free = crate::allocator::memory_stats().buddy_free as u64 / BLOCK_SIZE;
+        Ok(FsStats { block_size: BLOCK_SIZE, blocks: self.size().div_ceil(BLOCK_SIZE) + free, free_blocks: free })
+    }
+}
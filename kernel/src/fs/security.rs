@@ -0,0 +1,199 @@
+// File security
+//
+// Every VFS path has an effective security descriptor: the one stored for it,
+// or one inherited down the directory tree from the nearest ancestor that has
+// one. File systems with native ACL storage (NTFS $Secure) keep descriptors on
+// disk; for the others the VFS keeps them in a $Secure-format store of its own.
+// Well-known system directories carry built-in descriptors until an explicit
+// one is set.
+
+use crate::nt::security::{
+    Ace, AceFlags, AceType, Acl, SecurityDescriptor, SecurityDescriptorControl, Sid, WellKnownSids,
+    FILE_ALL_ACCESS, FILE_GENERIC_EXECUTE, FILE_GENERIC_MAPPING, FILE_GENERIC_READ,
+    FILE_GENERIC_WRITE, DELETE,
+};
+use super::ntfs::security::SecureStream;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const INHERIT_ALL: AceFlags = AceFlags::OBJECT_INHERIT_ACE.union(AceFlags::CONTAINER_INHERIT_ACE);
+const FILE_MODIFY: u32 = FILE_GENERIC_READ | FILE_GENERIC_WRITE | FILE_GENERIC_EXECUTE | DELETE;
+
+// Descriptors for files on file systems without native ACL storage
+pub struct SecurityStore {
+    descriptors: SecureStream,
+    security_ids: BTreeMap<String, u32>,
+}
+
+impl SecurityStore {
+    pub fn new() -> Self {
+        Self {
+            descriptors: SecureStream::new(),
+            security_ids: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        let id = self.security_ids.get(&path.to_lowercase())?;
+        self.descriptors.lookup(*id)
+    }
+
+    pub fn set(&mut self, path: &str, descriptor: &[u8]) {
+        let id = self.descriptors.insert(descriptor);
+        self.security_ids.insert(path.to_lowercase(), id);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.security_ids.remove(&path.to_lowercase());
+    }
+}
+
+/// Canonical VFS form of a path: forward slashes, a leading slash, no drive
+/// letter and no trailing slash
+pub fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = match path.get(1..2) {
+        Some(":") => &path[2..],
+        _ => &path[..],
+    };
+    let mut normalized = String::new();
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+pub fn parent_path(path: &str) -> Option<&str> {
+    if path == "/" {
+        return None;
+    }
+    match path.rfind('/') {
+        Some(0) => Some("/"),
+        Some(i) => Some(&path[..i]),
+        None => None,
+    }
+}
+
+fn full_access(sid: Sid) -> Ace {
+    Ace::new(AceType::AccessAllowed, INHERIT_ALL, FILE_ALL_ACCESS, sid)
+}
+
+fn descriptor(owner: Sid, aces: Vec<Ace>, protected: bool) -> SecurityDescriptor {
+    let mut dacl = Acl::new();
+    for ace in aces {
+        dacl.add_ace(ace);
+    }
+    let mut sd = SecurityDescriptor::new();
+    sd.set_owner(owner);
+    sd.set_group(WellKnownSids::system_sid());
+    sd.set_dacl(dacl);
+    if protected {
+        sd.control.insert(SecurityDescriptorControl::SE_DACL_PROTECTED);
+    }
+    sd
+}
+
+/// Built-in descriptors for the volume root and well-known directories
+pub fn builtin_descriptor(path: &str) -> Option<SecurityDescriptor> {
+    let system = WellKnownSids::system_sid();
+    let admins = WellKnownSids::administrators_sid();
+    let users = WellKnownSids::users_sid();
+    let lower = path.to_lowercase();
+
+    let sd = match lower.as_str() {
+        "/" => descriptor(
+            admins.clone(),
+            vec![
+                full_access(system),
+                full_access(admins),
+                Ace::new(AceType::AccessAllowed, INHERIT_ALL, FILE_MODIFY, users),
+                // Whoever creates a file gets full control of it
+                Ace::new(
+                    AceType::AccessAllowed,
+                    INHERIT_ALL | AceFlags::INHERIT_ONLY_ACE,
+                    FILE_ALL_ACCESS,
+                    WellKnownSids::creator_owner_sid(),
+                ),
+            ],
+            false,
+        ),
+        "/windows" | "/program files" | "/users" => descriptor(
+            admins.clone(),
+            vec![
+                full_access(system),
+                full_access(admins),
+                Ace::new(
+                    AceType::AccessAllowed,
+                    INHERIT_ALL,
+                    FILE_GENERIC_READ | FILE_GENERIC_EXECUTE,
+                    users,
+                ),
+            ],
+            true,
+        ),
+        // Registry hives
+        "/windows/system32/config" => descriptor(system.clone(), vec![full_access(system)], true),
+        // Audit log: written by the kernel only, readable by administrators
+        "/windows/system32/winevt/logs" => descriptor(
+            system.clone(),
+            vec![
+                full_access(system),
+                Ace::new(AceType::AccessAllowed, INHERIT_ALL, FILE_GENERIC_READ, admins),
+            ],
+            true,
+        ),
+        "/users/public" => descriptor(
+            admins.clone(),
+            vec![
+                full_access(system),
+                full_access(admins),
+                Ace::new(AceType::AccessAllowed, INHERIT_ALL, FILE_MODIFY, users),
+            ],
+            true,
+        ),
+        _ => {
+            // Profile directories belong to their user
+            let name = lower.strip_prefix("/users/").filter(|rest| !rest.contains('/'))?;
+            let user = crate::security::accounts::account_sid(name)?;
+            descriptor(
+                user.clone(),
+                vec![full_access(system), full_access(admins), full_access(user)],
+                true,
+            )
+        }
+    };
+    Some(sd)
+}
+
+/// Descriptor of a child that has none of its own: everything inheritable
+/// from the parent, under the parent's owner
+pub fn inherit_from(parent: &SecurityDescriptor, is_container: bool) -> SecurityDescriptor {
+    let owner = parent.owner_sid.clone().unwrap_or_else(WellKnownSids::system_sid);
+    let group = parent.group_sid.clone().unwrap_or_else(WellKnownSids::system_sid);
+
+    let mut sd = SecurityDescriptor::new();
+    let dacl = parent
+        .dacl
+        .as_ref()
+        .map(|dacl| dacl.inherit(is_container, &owner, &group, &FILE_GENERIC_MAPPING).unwrap_or_else(Acl::new));
+    if let Some(dacl) = dacl {
+        sd.set_dacl(dacl);
+        sd.control.insert(SecurityDescriptorControl::SE_DACL_AUTO_INHERITED);
+    }
+    if let Some(sacl) = parent
+        .sacl
+        .as_ref()
+        .and_then(|sacl| sacl.inherit(is_container, &owner, &group, &FILE_GENERIC_MAPPING))
+    {
+        sd.set_sacl(sacl);
+    }
+    sd.set_owner(owner);
+    sd.set_group(group);
+    sd
+}
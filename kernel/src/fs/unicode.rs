@@ -0,0 +1,122 @@
+//! File names in UTF-8 and UTF-16
+//!
+//! The VFS names files in UTF-8, as paths come from the syscalls. NTFS,
+//! FAT long names and the Win32 W calls hold them in UTF-16, little-endian
+//! on disk, and are converted here. Windows lets a name hold half of a
+//! surrogate pair; such a unit reads as U+FFFD.
+//!
+//! NTFS compares names without case a UTF-16 unit at a time, through the
+//! volume's upcase table, kept in $UpCase. `UpCase::standard` is the same
+//! mapping built from Unicode's simple case mappings, used for FAT and for
+//! volumes whose table cannot be read.
+//!
+//! FAT short names are in an OEM code page, taken to be 437, which is
+//! also the VGA text font's.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use spin::Once;
+
+/// The longest name NTFS and FAT hold, in UTF-16 units
+pub const MAX_NAME_UNITS: usize = 255;
+
+// An upcase table has an entry for every UTF-16 unit
+const UPCASE_ENTRIES: usize = 0x1_0000;
+
+// Code page 437 from 80h; below that it is ASCII
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}",
+);
+
+pub fn decode_utf16(units: impl IntoIterator<Item = u16>) -> String {
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+/// Little-endian UTF-16 as a string; an odd byte at the end is dropped
+pub fn decode_utf16le(bytes: &[u8]) -> String {
+    decode_utf16(bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])))
+}
+
+pub fn encode_utf16le(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Length in UTF-16 units, as NTFS and FAT count a name
+pub fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+pub fn from_cp437(byte: u8) -> char {
+    match byte {
+        0x80.. => CP437_HIGH.chars().nth(byte as usize - 0x80).unwrap_or(char::REPLACEMENT_CHARACTER),
+        _ => byte as char,
+    }
+}
+
+/// The code page 437 byte for `c`, if it has one
+pub fn to_cp437(c: char) -> Option<u8> {
+    if c.is_ascii() {
+        return Some(c as u8);
+    }
+    CP437_HIGH.chars().position(|high| high == c).map(|index| 0x80 + index as u8)
+}
+
+/// Whether a new file may take `name`: not empty, `.` or `..`, without
+/// control characters, and short enough for NTFS and FAT
+pub fn valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.chars().any(char::is_control) && utf16_len(name) <= MAX_NAME_UNITS
+}
+
+/// A table of each UTF-16 unit's capital
+pub struct UpCase {
+    table: Vec<u16>,
+}
+
+impl UpCase {
+    /// The table from Unicode's simple case mappings, as Windows formats
+    /// volumes with
+    pub fn standard() -> &'static UpCase {
+        static STANDARD: Once<UpCase> = Once::new();
+        STANDARD.call_once(|| UpCase { table: (0..UPCASE_ENTRIES).map(|unit| simple_upcase(unit as u16)).collect() })
+    }
+
+    /// A table as $UpCase holds it, 128 KiB of little-endian units
+    pub fn parse(data: &[u8]) -> Option<UpCase> {
+        if data.len() < UPCASE_ENTRIES * 2 {
+            return None;
+        }
+        let table = data[..UPCASE_ENTRIES * 2].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+        Some(UpCase { table: table.collect() })
+    }
+
+    pub fn upcase(&self, unit: u16) -> u16 {
+        self.table[unit as usize]
+    }
+
+    /// Order names as an NTFS directory index does
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        let upcased = |name: &'_ str| name.encode_utf16().map(|unit| self.upcase(unit)).collect::<Vec<u16>>();
+        upcased(a).cmp(&upcased(b))
+    }
+
+    pub fn eq_ignore_case(&self, a: &str, b: &str) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+}
+
+// A unit's capital, where Unicode gives a single one that is also a
+// single unit; surrogates and everything else are left alone
+fn simple_upcase(unit: u16) -> u16 {
+    let Some(c) = char::from_u32(unit as u32) else {
+        return unit;
+    };
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(capital), None) if capital.len_utf16() == 1 => capital as u32 as u16,
+        _ => unit,
+    }
+}
@@ -1,28 +1,127 @@
-use super::{FileSystem, FileSystemError, FileInfo};
+use super::{FileSystem, FileSystemError, FileInfo, FileType, FsControl, FsControlReply, FsStats};
+use super::{copy_at, split_stream, StreamInfo};
+use super::security::{self as file_security, SecurityStore};
+use super::locks;
+use super::notify::{self, CHANGE_DIR_NAME, CHANGE_FILE_NAME, CHANGE_LAST_WRITE, CHANGE_SECURITY, CHANGE_SIZE};
+use crate::nt::security::{
+    query_security_access, set_security_access, SecurityDescriptor, FILE_GENERIC_MAPPING,
+    FILE_LIST_DIRECTORY, FILE_READ_DATA, FILE_READ_ATTRIBUTES, FILE_WRITE_DATA, FILE_ADD_FILE, FILE_APPEND_DATA,
+    FILE_WRITE_EA, FILE_WRITE_ATTRIBUTES, FILE_DELETE_CHILD, DELETE, WRITE_DAC, WRITE_OWNER,
+    GENERIC_WRITE, GENERIC_ALL, OWNER_SECURITY_INFORMATION, SECURITY_MANAGER,
+};
+use crate::security::audit::{self, EventDetails, SecurityEvent, Severity};
 use alloc::vec::Vec;
-use alloc::string::String;
+use alloc::format;
+use alloc::string::{String, ToString};
 use alloc::boxed::Box;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 
+// Rights that count as writing for a sandbox's read-only paths
+const SANDBOX_WRITE_ACCESS: u32 = FILE_WRITE_DATA
+    | FILE_APPEND_DATA
+    | FILE_WRITE_EA
+    | FILE_WRITE_ATTRIBUTES
+    | FILE_DELETE_CHILD
+    | DELETE
+    | WRITE_DAC
+    | WRITE_OWNER
+    | GENERIC_WRITE
+    | GENERIC_ALL;
+
+// Names are UTF-8 here, and a new file's must also fit the UTF-16 names
+// of NTFS and FAT; see `unicode::valid_name`
+fn check_new_name(path: &str) -> Result<(), FileSystemError> {
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    if !super::unicode::valid_name(name) {
+        return Err(FileSystemError::InvalidPath);
+    }
+    Ok(())
+}
+
+// Global path of a path named by the calling process, through its mount
+// namespace
+fn namespace_path(path: &str) -> String {
+    let pid = crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0);
+    crate::container::nsproxy::resolve_path(pid, path)
+}
+
+// Whether `path` is at or below `mount_point`, on a component boundary
+fn covers(mount_point: &str, path: &str) -> bool {
+    match path.strip_prefix(mount_point) {
+        Some(rest) => mount_point.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+// Read a file, or the named stream a path ends in
+fn read_path(fs: &mut dyn FileSystem, path: &str) -> Result<Vec<u8>, FileSystemError> {
+    match split_stream(path) {
+        (file, Some(stream)) => fs.read_named_stream(file, stream),
+        (file, None) => fs.read_file(file),
+    }
+}
+
+// Write a file, or the named stream a path ends in. A stream of a file
+// that does not exist yet creates the file, empty.
+fn write_path(fs: &mut dyn FileSystem, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
+    match split_stream(path) {
+        (file, Some(stream)) => {
+            if fs.get_file_info(file).is_err() {
+                fs.write_file(file, &[])?;
+            }
+            fs.write_named_stream(file, stream, data)
+        }
+        (file, None) => fs.write_file(file, data),
+    }
+}
+
 pub struct VirtualFileSystem {
     filesystems: Vec<(String, Box<dyn FileSystem + Send + Sync>)>,
+    security: SecurityStore,
 }
 
 impl VirtualFileSystem {
     pub fn new() -> Self {
         Self {
             filesystems: Vec::new(),
+            security: SecurityStore::new(),
         }
     }
 
     pub fn mount(&mut self, mount_point: String, fs: Box<dyn FileSystem + Send + Sync>) {
         self.filesystems.push((mount_point, fs));
+        // The deepest mount point covering a path wins
+        self.filesystems.sort_by_key(|(mount_point, _)| core::cmp::Reverse(mount_point.len()));
+    }
+
+    /// Take the file system off `mount_point`, dropping it; false if
+    /// nothing is mounted there
+    pub fn unmount(&mut self, mount_point: &str) -> bool {
+        let count = self.filesystems.len();
+        self.filesystems.retain(|(existing, _)| existing != mount_point);
+        self.filesystems.len() != count
+    }
+
+    pub fn is_mounted(&self, mount_point: &str) -> bool {
+        self.filesystems.iter().any(|(existing, _)| existing == mount_point)
+    }
+
+    /// Each mount point, in order, with its file system's type and, where
+    /// it can say, size and use
+    pub fn mounts(&self) -> Vec<(String, &'static str, Option<FsStats>)> {
+        let mut mounts: Vec<_> = self
+            .filesystems
+            .iter()
+            .map(|(mount_point, fs)| (mount_point.clone(), fs.fs_type(), fs.statfs().ok()))
+            .collect();
+        mounts.sort_by(|a, b| a.0.cmp(&b.0));
+        mounts
     }
 
     fn find_filesystem<'a>(&'a self, path: &'a str) -> Option<(&'a dyn FileSystem, &'a str)> {
         for (mount_point, fs) in &self.filesystems {
-            if path.starts_with(mount_point.as_str()) {
+            if covers(mount_point, path) {
                 let relative_path = &path[mount_point.len()..];
                 return Some((fs.as_ref(), relative_path));
             }
@@ -32,7 +131,7 @@ impl VirtualFileSystem {
 
     fn find_filesystem_mut<'a>(&'a mut self, path: &'a str) -> Option<(&'a mut dyn FileSystem, &'a str)> {
         for (mount_point, fs) in &mut self.filesystems {
-            if path.starts_with(mount_point.as_str()) {
+            if covers(mount_point, path) {
                 let relative_path = &path[mount_point.len()..];
                 return Some((fs.as_mut(), relative_path));
             }
@@ -40,29 +139,343 @@ impl VirtualFileSystem {
         None
     }
 
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FileSystemError> {
-        if let Some((fs, relative_path)) = self.find_filesystem(path) {
-            fs.read_file(relative_path)
-        } else {
-            Err(FileSystemError::NotFound)
-        }
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        let path = &namespace_path(path);
+        self.check_access(path, FILE_READ_DATA)?;
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        read_path(fs, relative_path)
     }
 
     pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
+        let path = &namespace_path(path);
+        // New files need FILE_ADD_FILE on their directory; a new stream of a
+        // file that exists is a write to the file
+        let file = split_stream(path).0;
+        let exists = self.exists(file);
+        let parent = file_security::normalize_path(file);
+        let parent = file_security::parent_path(&parent).unwrap_or("/").to_string();
+        if exists {
+            self.check_access(path, FILE_WRITE_DATA)?;
+            // Writing a whole file by path is stopped by any lock on it
+            locks::check(path, None, 0, u64::MAX, true)?;
+        } else {
+            check_new_name(file)?;
+            self.check_access(&parent, FILE_ADD_FILE)?;
+        }
+
         if let Some((fs, relative_path)) = self.find_filesystem_mut(path) {
-            fs.write_file(relative_path, data)
+            write_path(fs, relative_path, data)?;
         } else {
-            Err(FileSystemError::NotFound)
+            return Err(FileSystemError::NotFound);
+        }
+
+        if exists {
+            notify::changed(file, CHANGE_LAST_WRITE | CHANGE_SIZE);
+        } else {
+            self.assign_new_file_security(file, &parent);
+            notify::changed(file, CHANGE_FILE_NAME);
         }
+        Ok(())
     }
 
-    pub fn list_directory(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+    /// Check that the caller may read a regular file, and write it if
+    /// `write`, and give its global path for later unchecked access
+    pub fn open(&mut self, path: &str, write: bool) -> Result<String, FileSystemError> {
+        let path = namespace_path(path);
+        let access = if write { FILE_READ_DATA | FILE_WRITE_DATA } else { FILE_READ_DATA };
+        self.check_access(&path, access)?;
+        let (fs, relative_path) = self.find_filesystem_mut(&path).ok_or(FileSystemError::NotFound)?;
+        let (file, stream) = split_stream(relative_path);
+        if let Some(stream) = stream {
+            if !fs.list_streams(file)?.iter().any(|info| info.name == stream) {
+                return Err(FileSystemError::NotFound);
+            }
+        }
+        match fs.get_file_info(file)?.file_type {
+            FileType::Directory => Err(FileSystemError::InvalidPath),
+            _ => Ok(path),
+        }
+    }
+
+    pub fn list_directory(&mut self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        let path = &namespace_path(path);
+        self.check_access(path, FILE_LIST_DIRECTORY)?;
         if let Some((fs, relative_path)) = self.find_filesystem(path) {
             fs.list_directory(relative_path)
         } else {
             Err(FileSystemError::NotFound)
         }
     }
+
+    /// Watch a directory the caller may list for changes; see `notify`
+    pub fn watch_directory(&mut self, path: &str, subtree: bool, filter: u32) -> Result<u64, FileSystemError> {
+        let path = &namespace_path(path);
+        self.check_access(path, FILE_LIST_DIRECTORY)?;
+        let (fs, relative_path) = self.find_filesystem(path).ok_or(FileSystemError::NotFound)?;
+        if !matches!(fs.get_file_info(relative_path)?.file_type, FileType::Directory) {
+            return Err(FileSystemError::InvalidPath);
+        }
+        Ok(notify::watch(path, subtree, filter))
+    }
+
+    // Kernel-owned files such as the audit log are accessed without an access
+    // check: the current token belongs to whoever triggered the kernel work
+
+    pub fn read_file_unchecked(&mut self, path: &str) -> Result<Vec<u8>, FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        read_path(fs, relative_path)
+    }
+
+    /// Read part of a file into `buffer`, as the page cache fills a page. A
+    /// named stream is read whole and the part copied out of it.
+    pub fn read_at_unchecked(&mut self, path: &str, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        match split_stream(relative_path) {
+            (file, Some(stream)) => Ok(copy_at(&fs.read_named_stream(file, stream)?, offset, buffer)),
+            (file, None) => fs.read_at(file, offset, buffer),
+        }
+    }
+
+    pub fn file_size_unchecked(&mut self, path: &str) -> Result<u64, FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        match split_stream(relative_path) {
+            (file, Some(stream)) => Ok(fs.read_named_stream(file, stream)?.len() as u64),
+            (file, None) => fs.file_size(file),
+        }
+    }
+
+    pub fn write_file_unchecked(&mut self, path: &str, data: &[u8]) -> Result<(), FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        write_path(fs, relative_path, data)
+    }
+
+    pub fn list_directory_unchecked(&self, path: &str) -> Result<Vec<FileInfo>, FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem(path).ok_or(FileSystemError::NotFound)?;
+        fs.list_directory(relative_path)
+    }
+
+    pub fn create_directory_unchecked(&mut self, path: &str) -> Result<(), FileSystemError> {
+        check_new_name(path)?;
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        fs.create_directory(relative_path)?;
+        notify::changed(path, CHANGE_DIR_NAME);
+        Ok(())
+    }
+
+    pub fn block_map_unchecked(&self, path: &str) -> Result<(usize, Vec<(u64, u64)>), FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem(path).ok_or(FileSystemError::NotFound)?;
+        fs.block_map(relative_path)
+    }
+
+    pub fn fs_control_unchecked(&mut self, path: &str, request: FsControl) -> Result<FsControlReply, FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        fs.fs_control(relative_path, request)
+    }
+
+    pub fn delete_unchecked(&mut self, path: &str) -> Result<(), FileSystemError> {
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        match split_stream(relative_path) {
+            (file, Some(stream)) => {
+                fs.delete_named_stream(file, stream)?;
+                notify::changed(split_stream(path).0, CHANGE_SIZE);
+            }
+            (file, None) => {
+                let info = fs.get_file_info(file);
+                let directory = matches!(info, Ok(info) if matches!(info.file_type, FileType::Directory));
+                fs.delete(file)?;
+                let kind = if directory { CHANGE_DIR_NAME } else { CHANGE_FILE_NAME };
+                notify::changed(path, kind);
+            }
+        }
+        Ok(())
+    }
+
+    /// The data streams of a file the caller may read the attributes of: a
+    /// regular file's own data first, under an empty name, then its named
+    /// streams
+    pub fn list_streams(&mut self, path: &str) -> Result<Vec<StreamInfo>, FileSystemError> {
+        let path = &namespace_path(path);
+        self.check_access(path, FILE_READ_ATTRIBUTES)?;
+        let (fs, relative_path) = self.find_filesystem_mut(path).ok_or(FileSystemError::NotFound)?;
+        let file = split_stream(relative_path).0;
+        let info = fs.get_file_info(file)?;
+        let mut streams = Vec::new();
+        if !matches!(info.file_type, FileType::Directory) {
+            streams.push(StreamInfo { name: String::new(), size: info.size });
+        }
+        streams.extend(fs.list_streams(file)?);
+        Ok(streams)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        let path = split_stream(path).0;
+        self.find_filesystem(path)
+            .map_or(false, |(fs, relative_path)| fs.get_file_info(relative_path).is_ok())
+    }
+
+    fn is_directory(&self, path: &str) -> bool {
+        self.find_filesystem(path).map_or(false, |(fs, relative_path)| {
+            matches!(fs.get_file_info(relative_path), Ok(info) if matches!(info.file_type, FileType::Directory))
+        })
+    }
+
+    // Descriptor set explicitly on a path, on disk or in the VFS store,
+    // falling back to the built-in ones for system directories
+    fn stored_security(&mut self, path: &str) -> Option<SecurityDescriptor> {
+        let on_disk = self
+            .find_filesystem_mut(path)
+            .and_then(|(fs, relative_path)| fs.get_security(relative_path).ok().flatten());
+        let bytes = on_disk.or_else(|| self.security.get(path).map(|sd| sd.to_vec()));
+        match bytes {
+            Some(bytes) => SecurityDescriptor::from_self_relative(&bytes).ok(),
+            None => file_security::builtin_descriptor(path),
+        }
+    }
+
+    /// Effective security descriptor of a path: its own, or one inherited
+    /// from the nearest ancestor that has one
+    pub fn effective_security(&mut self, path: &str) -> SecurityDescriptor {
+        let path = file_security::normalize_path(path);
+        let mut ancestors = Vec::new();
+        let mut current = Some(path.as_str());
+        let mut sd = None;
+        while let Some(p) = current {
+            if let Some(stored) = self.stored_security(p) {
+                sd = Some(stored);
+                break;
+            }
+            ancestors.push(p.to_string());
+            current = file_security::parent_path(p);
+        }
+
+        // The root always has a built-in descriptor
+        let mut sd = sd.unwrap_or_else(SecurityDescriptor::new);
+        while let Some(child) = ancestors.pop() {
+            let is_container = !ancestors.is_empty() || self.is_directory(&child);
+            sd = file_security::inherit_from(&sd, is_container);
+        }
+        sd
+    }
+
+    /// Access check of the calling process's token against a path
+    pub fn check_access(&mut self, path: &str, desired_access: u32) -> Result<u32, FileSystemError> {
+        // A file's streams share its descriptor
+        let path = split_stream(path).0;
+        // The caller's sandbox is consulted before the file's ACL
+        let pid = crate::process::PROCESS_MANAGER.lock().current_process;
+        if let Some(pid) = pid {
+            let write = desired_access & SANDBOX_WRITE_ACCESS != 0;
+            crate::security::sandbox::check_path_access(pid.0 as u64, path, write)
+                .map_err(|_| FileSystemError::PermissionDenied)?;
+        }
+
+        let sd = self.effective_security(path);
+        let token = crate::process::current_token();
+        let manager = SECURITY_MANAGER.lock();
+        let result = manager.access_check(&sd, token, desired_access, &FILE_GENERIC_MAPPING);
+        let audit = manager.audit_required(&sd, token, desired_access, &FILE_GENERIC_MAPPING, result.is_ok());
+        drop(manager);
+
+        if audit {
+            let (event, severity) = match result {
+                Ok(_) => (SecurityEvent::FileAccessGranted, Severity::Info),
+                Err(_) => (SecurityEvent::FileAccessDenied, Severity::Warning),
+            };
+            let mut details = EventDetails::new();
+            details.target_path = Some(path.to_string());
+            details.additional_info.push(("access".to_string(), format!("{:#x}", desired_access)));
+            audit::log_event(event, severity, "Audited file access", details);
+        }
+
+        result.map_err(|_| {
+            audit::log_access_violation(path, "access check");
+            FileSystemError::PermissionDenied
+        })
+    }
+
+    // Give a newly created file the descriptor inherited from its directory,
+    // owned by its creator
+    fn assign_new_file_security(&mut self, path: &str, parent: &str) {
+        let parent_sd = self.effective_security(parent);
+        let is_container = self.is_directory(path);
+        let token = crate::process::current_token();
+        let sd = {
+            let manager = SECURITY_MANAGER.lock();
+            match manager.get_token(token) {
+                Some(token) => SecurityDescriptor::create_inherited(
+                    Some(&parent_sd),
+                    None,
+                    is_container,
+                    token,
+                    &FILE_GENERIC_MAPPING,
+                ),
+                None => return,
+            }
+        };
+        let _ = self.store_security(path, &sd);
+    }
+
+    fn store_security(&mut self, path: &str, sd: &SecurityDescriptor) -> Result<(), FileSystemError> {
+        let bytes = sd.to_self_relative();
+        let normalized = file_security::normalize_path(path);
+        let native = match self.find_filesystem_mut(path) {
+            Some((fs, relative_path)) => fs.set_security(relative_path, &bytes),
+            None => return Err(FileSystemError::NotFound),
+        };
+        match native {
+            Ok(()) => {
+                self.security.remove(&normalized);
+                Ok(())
+            }
+            Err(FileSystemError::NotSupported) => {
+                self.security.set(&normalized, &bytes);
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The parts of a file's descriptor selected by `security_information`
+    pub fn get_file_security(
+        &mut self,
+        path: &str,
+        security_information: u32,
+    ) -> Result<SecurityDescriptor, FileSystemError> {
+        let path = &namespace_path(path);
+        if !self.exists(path) && path != "/" {
+            return Err(FileSystemError::NotFound);
+        }
+        self.check_access(path, query_security_access(security_information))?;
+        Ok(self.effective_security(path).select(security_information))
+    }
+
+    /// Replace the parts of a file's descriptor selected by
+    /// `security_information`
+    pub fn set_file_security(
+        &mut self,
+        path: &str,
+        security_information: u32,
+        new: &SecurityDescriptor,
+    ) -> Result<(), FileSystemError> {
+        let path = &namespace_path(path);
+        if !self.exists(path) && path != "/" {
+            return Err(FileSystemError::NotFound);
+        }
+        self.check_access(path, set_security_access(security_information))?;
+        if security_information & OWNER_SECURITY_INFORMATION != 0 {
+            let owner = new.owner_sid.as_ref().ok_or(FileSystemError::InvalidPath)?;
+            let token = crate::process::current_token();
+            if SECURITY_MANAGER.lock().validate_owner(token, owner).is_err() {
+                return Err(FileSystemError::PermissionDenied);
+            }
+        }
+
+        let mut sd = self.effective_security(path);
+        sd.merge(security_information, new);
+        self.store_security(path, &sd)?;
+        notify::changed(path, CHANGE_SECURITY);
+        Ok(())
+    }
 }
 
 lazy_static! {
@@ -0,0 +1,80 @@
+// Panel backlight
+//
+// Intel integrated graphics drive the panel's backlight PWM from the PCH
+// display engine: BLC_PWM_PCH_CTL2 holds the period in its top half and
+// the duty cycle in the bottom half. The period is the firmware's and is
+// kept; brightness is the duty cycle as a share of it. Only the first
+// Intel display function is driven, and only when firmware has left its
+// PWM on; other panels have no brightness control here.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PCIE_CONTROLLER, PCI_CLASS_DISPLAY};
+
+const INTEL: u16 = 0x8086;
+
+const BLC_PWM_PCH_CTL1: u64 = 0xC8250;
+const BLC_PWM_PCH_CTL2: u64 = 0xC8254;
+const PWM_ENABLE: u32 = 1 << 31;
+
+// Lowest brightness set, so the panel never goes dark
+const MIN_PERCENT: u8 = 5;
+
+// Register base of the display function, once found
+static MMIO: Mutex<Option<u64>> = Mutex::new(None);
+
+fn read(base: u64, register: u64) -> u32 {
+    unsafe { read_volatile((PHYS_MEM_OFFSET + base + register) as *const u32) }
+}
+
+fn write(base: u64, register: u64, value: u32) {
+    unsafe { write_volatile((PHYS_MEM_OFFSET + base + register) as *mut u32, value) }
+}
+
+// The display's registers, if its backlight PWM is running
+fn mmio() -> Result<u64, &'static str> {
+    let mut mmio = MMIO.lock();
+    if mmio.is_none() {
+        let controller = PCIE_CONTROLLER.lock();
+        let display = controller
+            .devices()
+            .iter()
+            .find(|d| d.vendor_id == INTEL && d.class == PCI_CLASS_DISPLAY)
+            .ok_or("no Intel display")?;
+        *mmio = Some((display.bars[0] as u64 & !0xF) | ((display.bars[1] as u64) << 32));
+    }
+    let base = mmio.ok_or("no Intel display")?;
+    if base == 0 {
+        return Err("display has no register BAR");
+    }
+    if read(base, BLC_PWM_PCH_CTL1) & PWM_ENABLE == 0 || read(base, BLC_PWM_PCH_CTL2) >> 16 == 0 {
+        return Err("backlight PWM not set up by firmware");
+    }
+    Ok(base)
+}
+
+/// Brightness in percent
+pub fn percent() -> Result<u8, &'static str> {
+    let control = read(mmio()?, BLC_PWM_PCH_CTL2);
+    let (period, duty) = (control >> 16, control & 0xFFFF);
+    Ok((duty.min(period) * 100 / period) as u8)
+}
+
+/// Set brightness, returning the percentage set
+pub fn set_percent(percent: u8) -> Result<u8, &'static str> {
+    let base = mmio()?;
+    let percent = percent.clamp(MIN_PERCENT, 100);
+    let period = read(base, BLC_PWM_PCH_CTL2) >> 16;
+    let duty = (period * percent as u32).div_ceil(100);
+    write(base, BLC_PWM_PCH_CTL2, period << 16 | duty);
+    Ok(percent)
+}
+
+/// Raise or lower brightness by `delta` percent
+pub fn step(delta: i8) -> Result<u8, &'static str> {
+    let current = percent()? as i16;
+    set_percent((current + delta as i16).clamp(0, 100) as u8)
+}
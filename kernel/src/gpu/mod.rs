@@ -18,6 +18,8 @@ pub mod drm;
 pub mod kms;
 pub mod opengl;
 pub mod video;
+pub mod backlight;
+pub mod raster;
 
 // GPU Vendor IDs
 pub const VENDOR_INTEL: u16 = 0x8086;
@@ -247,6 +249,14 @@ pub trait GpuDriver: Send + Sync {
                width: u32, height: u32) -> Result<(), &'static str>;
     fn fill_2d(&mut self, dst: &BufferObject, x: u32, y: u32, 
                width: u32, height: u32, color: u32) -> Result<(), &'static str>;
+    
+    // Run a fixed-function 3D command stream (see `raster`) into `target`,
+    // a framebuffer of `width` by `height`; engines that cannot take one
+    // leave it to the CPU
+    fn execute_3d(&mut self, _commands: &[u32], _target: &BufferObject,
+                  _width: u32, _height: u32) -> Result<(), &'static str> {
+        Err("3D command streams not supported")
+    }
 }
 
 // GPU Manager
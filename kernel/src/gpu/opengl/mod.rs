@@ -9,6 +9,7 @@ pub mod context;
 pub mod pipeline;
 pub mod texture;
 pub mod buffer;
+pub mod soft;
 
 // OpenGL Version Support
 #[derive(Debug, Clone, Copy, PartialEq)]
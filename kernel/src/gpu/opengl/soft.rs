@@ -0,0 +1,290 @@
+//! Software rasterization for OpenGL
+//!
+//! Points, lines and triangles in window coordinates, for contexts with no
+//! 3D engine under them. Colors and texture coordinates are interpolated
+//! perspective-correctly, the texture is sampled and combined as the
+//! texture environment says, and the alpha test runs before the depth test
+//! and blending that `raster` does for Direct3D.
+
+use alloc::vec::Vec;
+
+use crate::gpu::raster::{self, RenderTarget, State};
+
+/// What a texture coordinate outside 0 to 1 reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+}
+
+/// How the texture's color combines with the fragment's, as glTexEnv sets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combine {
+    Modulate,
+    Replace,
+    Decal,
+    /// Toward the environment color, [a, r, g, b]
+    Blend([f32; 4]),
+    Add,
+}
+
+/// A texture's base level, ARGB, rows from t = 0 up
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<u32>,
+    /// Whether the texels carry color, and alpha; an alpha texture leaves
+    /// the fragment's color alone, and an RGB or luminance one its alpha
+    pub has_color: bool,
+    pub has_alpha: bool,
+    pub min_linear: bool,
+    pub mag_linear: bool,
+    pub wrap_s: Wrap,
+    pub wrap_t: Wrap,
+}
+
+impl Texture {
+    /// An empty texture with GL's default parameters
+    pub fn new() -> Self {
+        Texture {
+            width: 0,
+            height: 0,
+            texels: Vec::new(),
+            has_color: true,
+            has_alpha: true,
+            min_linear: false,
+            mag_linear: true,
+            wrap_s: Wrap::Repeat,
+            wrap_t: Wrap::Repeat,
+        }
+    }
+
+    fn texel(&self, x: i32, y: i32) -> [f32; 4] {
+        let wrap = |v: i32, size: usize, mode: Wrap| match mode {
+            Wrap::Repeat => v.rem_euclid(size as i32) as usize,
+            Wrap::Clamp => v.clamp(0, size as i32 - 1) as usize,
+        };
+        let (x, y) = (wrap(x, self.width, self.wrap_s), wrap(y, self.height, self.wrap_t));
+        raster::unpack(self.texels[y * self.width + x])
+    }
+
+    /// The color at (s, t), [a, r, g, b], filtered for a texture shown
+    /// larger than it is or not
+    pub fn sample(&self, s: f32, t: f32, magnified: bool) -> [f32; 4] {
+        if self.width == 0 || self.height == 0 {
+            return [1.0; 4];
+        }
+        let (u, v) = (s * self.width as f32, t * self.height as f32);
+        let linear = if magnified { self.mag_linear } else { self.min_linear };
+        if !linear {
+            return self.texel(floor(u), floor(v));
+        }
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x, y) = (floor(u), floor(v));
+        let (fx, fy) = (u - x as f32, v - y as f32);
+        let [c00, c10, c01, c11] = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| self.texel(x + dx, y + dy));
+        core::array::from_fn(|i| {
+            let top = c00[i] + (c10[i] - c00[i]) * fx;
+            let bottom = c01[i] + (c11[i] - c01[i]) * fx;
+            top + (bottom - top) * fy
+        })
+    }
+}
+
+impl Default for Texture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Round toward minus infinity, for coordinates well inside an i32
+fn floor(x: f32) -> i32 {
+    let truncated = x as i32;
+    if (truncated as f32) > x {
+        truncated - 1
+    } else {
+        truncated
+    }
+}
+
+/// A vertex in window coordinates, y down, with depth from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    /// One over clip w, which the other attributes are interpolated by
+    pub inv_w: f32,
+    /// [a, r, g, b]
+    pub color: [f32; 4],
+    pub s: f32,
+    pub t: f32,
+}
+
+/// What happens to each fragment
+pub struct Pipeline<'a> {
+    pub state: State,
+    pub scissor: (usize, usize, usize, usize),
+    pub texture: Option<(&'a Texture, Combine)>,
+    /// A `raster::CMP_*` function and the alpha it compares with
+    pub alpha_test: Option<(u32, f32)>,
+}
+
+impl Pipeline<'_> {
+    // The fragment's color after texturing, or None if the alpha test
+    // throws it away
+    fn shade(&self, color: [f32; 4], s: f32, t: f32, magnified: bool) -> Option<[f32; 4]> {
+        let color = match self.texture {
+            Some((texture, combine)) => {
+                let texel = texture.sample(s, t, magnified);
+                combine_texel(texture, combine, color, texel)
+            }
+            None => color,
+        };
+        match self.alpha_test {
+            Some((func, reference)) if !raster::compare(func, color[0], reference) => None,
+            _ => Some(color),
+        }
+    }
+
+    fn plot(
+        &self,
+        target: &mut RenderTarget,
+        (x, y): (usize, usize),
+        z: f32,
+        color: [f32; 4],
+        st: (f32, f32),
+        mag: bool,
+    ) {
+        let (x0, y0, x1, y1) = self.scissor;
+        if x < x0 || x >= x1 || y < y0 || y >= y1 {
+            return;
+        }
+        if let Some(color) = self.shade(color, st.0, st.1, mag) {
+            raster::fragment(target, &self.state, y * target.width + x, z, color);
+        }
+    }
+}
+
+// The texture environment's function, as in the GL 1.x tables
+fn combine_texel(texture: &Texture, combine: Combine, fragment: [f32; 4], texel: [f32; 4]) -> [f32; 4] {
+    let [af, rf, gf, bf] = fragment;
+    let mut out = match combine {
+        Combine::Replace => texel,
+        Combine::Modulate => core::array::from_fn(|i| fragment[i] * texel[i]),
+        Combine::Decal => {
+            let at = if texture.has_alpha { texel[0] } else { 1.0 };
+            [af, rf + (texel[1] - rf) * at, gf + (texel[2] - gf) * at, bf + (texel[3] - bf) * at]
+        }
+        Combine::Blend(env) => {
+            let mut out: [f32; 4] = core::array::from_fn(|i| fragment[i] * (1.0 - texel[i]) + env[i] * texel[i]);
+            out[0] = af * texel[0];
+            out
+        }
+        Combine::Add => {
+            let mut out: [f32; 4] = core::array::from_fn(|i| fragment[i] + texel[i]);
+            out[0] = af * texel[0];
+            out
+        }
+    };
+    if !texture.has_color {
+        out[1..].copy_from_slice(&fragment[1..]);
+    }
+    if !texture.has_alpha {
+        out[0] = af;
+    }
+    out
+}
+
+// Twice the signed area of (from, to, (x, y)); positive when the point is
+// to the right of the edge as y grows down
+fn edge(from: &Vertex, to: &Vertex, x: f32, y: f32) -> f32 {
+    (to.x - from.x) * (y - from.y) - (to.y - from.y) * (x - from.x)
+}
+
+fn top_left(from: &Vertex, to: &Vertex) -> bool {
+    (from.y == to.y && to.x < from.x) || to.y > from.y
+}
+
+/// Twice the triangle's area on the screen, positive when it runs clockwise
+/// there
+pub fn signed_area(a: &Vertex, b: &Vertex, c: &Vertex) -> f32 {
+    edge(a, b, c.x, c.y)
+}
+
+// Whether the texture is shown larger than it is, from how many texels the
+// triangle covers against how many pixels
+fn magnified(pipeline: &Pipeline, a: &Vertex, b: &Vertex, c: &Vertex, pixels: f32) -> bool {
+    pipeline.texture.map_or(true, |(texture, _)| {
+        let texels = ((b.s - a.s) * (c.t - a.t) - (b.t - a.t) * (c.s - a.s)) * (texture.width * texture.height) as f32;
+        texels.max(-texels) <= pixels
+    })
+}
+
+/// Fill the pixels whose centers are inside, by the top-left rule
+pub fn triangle(target: &mut RenderTarget, pipeline: &Pipeline, a: &Vertex, b: &Vertex, c: &Vertex) {
+    let area = signed_area(a, b, c);
+    if area == 0.0 || !area.is_finite() {
+        return;
+    }
+    let (b, c) = if area < 0.0 { (c, b) } else { (b, c) };
+    let area = area.max(-area);
+    let mag = magnified(pipeline, a, b, c, area);
+
+    let (x0, y0, x1, y1) = pipeline.scissor;
+    let clamp = |v: f32, low: usize, high: usize| v.max(low as f32).min(high as f32) as usize;
+    let left = clamp(a.x.min(b.x).min(c.x), x0, x1);
+    let right = (clamp(a.x.max(b.x).max(c.x), x0, x1) + 1).min(x1);
+    let top = clamp(a.y.min(b.y).min(c.y), y0, y1);
+    let bottom = (clamp(a.y.max(b.y).max(c.y), y0, y1) + 1).min(y1);
+
+    for y in top..bottom {
+        let py = y as f32 + 0.5;
+        for x in left..right {
+            let px = x as f32 + 0.5;
+            let weights = [edge(b, c, px, py), edge(c, a, px, py), edge(a, b, px, py)];
+            let edges = [(b, c), (c, a), (a, b)];
+            let inside = weights.iter().zip(edges).all(|(&w, (from, to))| w > 0.0 || (w == 0.0 && top_left(from, to)));
+            if !inside {
+                continue;
+            }
+            let [wa, wb, wc] = weights.map(|w| w / area);
+            let z = a.z * wa + b.z * wb + c.z * wc;
+            // Weights in clip space, so attributes follow the perspective
+            let q = wa * a.inv_w + wb * b.inv_w + wc * c.inv_w;
+            let [pa, pb, pc] = [wa * a.inv_w / q, wb * b.inv_w / q, wc * c.inv_w / q];
+            let color = core::array::from_fn(|i| a.color[i] * pa + b.color[i] * pb + c.color[i] * pc);
+            let st = (a.s * pa + b.s * pb + c.s * pc, a.t * pa + b.t * pb + c.t * pc);
+            pipeline.plot(target, (x, y), z, color, st, mag);
+        }
+    }
+}
+
+// Longest line drawn, past any screen
+const MAX_LINE_STEPS: usize = 1 << 14;
+
+/// Step a one-pixel line from `a` to `b`
+pub fn line(target: &mut RenderTarget, pipeline: &Pipeline, a: &Vertex, b: &Vertex) {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let steps = (dx.max(-dx).max(dy).max(-dy) as usize + 1).min(MAX_LINE_STEPS);
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let (x, y) = (a.x + dx * t, a.y + dy * t);
+        if x < 0.0 || y < 0.0 {
+            continue;
+        }
+        let z = a.z + (b.z - a.z) * t;
+        let q = (1.0 - t) * a.inv_w + t * b.inv_w;
+        let p = t * b.inv_w / q;
+        let color = core::array::from_fn(|i| a.color[i] + (b.color[i] - a.color[i]) * p);
+        let st = (a.s + (b.s - a.s) * p, a.t + (b.t - a.t) * p);
+        pipeline.plot(target, (x as usize, y as usize), z, color, st, true);
+    }
+}
+
+/// Draw the pixel `v` is in
+pub fn point(target: &mut RenderTarget, pipeline: &Pipeline, v: &Vertex) {
+    if v.x >= 0.0 && v.y >= 0.0 {
+        pipeline.plot(target, (v.x as usize, v.y as usize), v.z, v.color, (v.s, v.t), true);
+    }
+}
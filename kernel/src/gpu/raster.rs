@@ -0,0 +1,370 @@
+//! Fixed-function 3D command streams
+//!
+//! A 3D client lowers each frame to a stream of dword packets: clears,
+//! render state, and primitives whose vertices are already transformed,
+//! lit and clipped to the near plane, in pixels with depth from 0 to 1.
+//! That is all a fixed-function pipeline leaves for the rasterizer, so a
+//! driver can hand the stream to its 3D engine with little more than
+//! relocation; one that can takes it through `GpuDriver::execute_3d`.
+//! Where none can, `execute` runs it on the CPU into a `RenderTarget`.
+//!
+//! Each packet is a header, the opcode in the high half and the payload's
+//! length in dwords in the low, then the payload. Floats go as their bits.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+const OP_CLEAR: u32 = 1;
+const OP_STATE: u32 = 2;
+const OP_SCISSOR: u32 = 3;
+const OP_DRAW: u32 = 4;
+
+// Dwords per vertex in a draw packet
+const VERTEX_DWORDS: usize = 4;
+// Vertices in one draw packet, a whole number of points, lines and
+// triangles
+const MAX_DRAW_VERTICES: usize = (0xFFFF - 1) / VERTEX_DWORDS / 6 * 6;
+
+/// What a clear packet clears
+pub const CLEAR_COLOR: u32 = 0x1;
+pub const CLEAR_DEPTH: u32 = 0x2;
+
+/// Render state flags
+pub const STATE_DEPTH_TEST: u32 = 0x1;
+pub const STATE_DEPTH_WRITE: u32 = 0x2;
+pub const STATE_BLEND: u32 = 0x4;
+
+/// Depth comparisons, with D3DCMP_*'s values
+pub const CMP_NEVER: u32 = 1;
+pub const CMP_LESS: u32 = 2;
+pub const CMP_EQUAL: u32 = 3;
+pub const CMP_LESS_EQUAL: u32 = 4;
+pub const CMP_GREATER: u32 = 5;
+pub const CMP_NOT_EQUAL: u32 = 6;
+pub const CMP_GREATER_EQUAL: u32 = 7;
+pub const CMP_ALWAYS: u32 = 8;
+
+/// Blend factors, with D3DBLEND_*'s values
+pub const BLEND_ZERO: u32 = 1;
+pub const BLEND_ONE: u32 = 2;
+pub const BLEND_SRC_COLOR: u32 = 3;
+pub const BLEND_INV_SRC_COLOR: u32 = 4;
+pub const BLEND_SRC_ALPHA: u32 = 5;
+pub const BLEND_INV_SRC_ALPHA: u32 = 6;
+pub const BLEND_DEST_ALPHA: u32 = 7;
+pub const BLEND_INV_DEST_ALPHA: u32 = 8;
+pub const BLEND_DEST_COLOR: u32 = 9;
+pub const BLEND_INV_DEST_COLOR: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Primitive {
+    Points = 1,
+    Lines = 2,
+    Triangles = 3,
+}
+
+/// A vertex as the rasterizer takes it: position in pixels, depth from 0
+/// to 1, and ARGB color
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub color: u32,
+}
+
+/// Render state for the primitives after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub flags: u32,
+    pub depth_func: u32,
+    pub src_blend: u32,
+    pub dest_blend: u32,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            flags: STATE_DEPTH_TEST | STATE_DEPTH_WRITE,
+            depth_func: CMP_LESS_EQUAL,
+            src_blend: BLEND_ONE,
+            dest_blend: BLEND_ZERO,
+        }
+    }
+}
+
+/// Builds a stream
+#[derive(Debug, Default)]
+pub struct StreamBuilder {
+    dwords: Vec<u32>,
+}
+
+impl StreamBuilder {
+    pub fn new() -> Self {
+        StreamBuilder { dwords: Vec::new() }
+    }
+
+    fn packet(&mut self, opcode: u32, payload: &[u32]) {
+        self.dwords.push(opcode << 16 | payload.len() as u32);
+        self.dwords.extend_from_slice(payload);
+    }
+
+    /// Clear the rectangle from (x0, y0) to before (x1, y1)
+    pub fn clear(&mut self, rect: (u32, u32, u32, u32), what: u32, color: u32, depth: f32) {
+        let (x0, y0, x1, y1) = rect;
+        self.packet(OP_CLEAR, &[x0, y0, x1, y1, what, color, depth.to_bits()]);
+    }
+
+    pub fn state(&mut self, state: &State) {
+        self.packet(OP_STATE, &[state.flags, state.depth_func, state.src_blend, state.dest_blend]);
+    }
+
+    /// Draw only inside the rectangle from (x0, y0) to before (x1, y1)
+    pub fn scissor(&mut self, rect: (u32, u32, u32, u32)) {
+        let (x0, y0, x1, y1) = rect;
+        self.packet(OP_SCISSOR, &[x0, y0, x1, y1]);
+    }
+
+    /// Draw `vertices` as a list of `primitive`s
+    pub fn draw(&mut self, primitive: Primitive, vertices: &[Vertex]) {
+        if vertices.is_empty() {
+            return;
+        }
+        // A packet's length must fit its header, so long lists are split
+        // between whole primitives
+        for chunk in vertices.chunks(MAX_DRAW_VERTICES) {
+            let mut payload = Vec::with_capacity(1 + chunk.len() * VERTEX_DWORDS);
+            payload.push(primitive as u32);
+            for vertex in chunk {
+                payload.extend_from_slice(&[vertex.x.to_bits(), vertex.y.to_bits(), vertex.z.to_bits(), vertex.color]);
+            }
+            self.packet(OP_DRAW, &payload);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dwords.is_empty()
+    }
+
+    pub fn build(self) -> Vec<u32> {
+        self.dwords
+    }
+}
+
+/// Where the CPU draws: ARGB color and a depth buffer the same size
+pub struct RenderTarget {
+    pub width: usize,
+    pub height: usize,
+    pub color: Vec<u32>,
+    pub depth: Vec<f32>,
+}
+
+impl RenderTarget {
+    pub fn new(width: usize, height: usize) -> Self {
+        RenderTarget { width, height, color: vec![0xFF00_0000; width * height], depth: vec![1.0; width * height] }
+    }
+}
+
+// The state execution has reached
+struct Context {
+    state: State,
+    scissor: (usize, usize, usize, usize),
+}
+
+/// Run a stream on the CPU
+pub fn execute(stream: &[u32], target: &mut RenderTarget) -> Result<(), &'static str> {
+    let mut context = Context { state: State::default(), scissor: (0, 0, target.width, target.height) };
+    let mut rest = stream;
+    while let Some((&header, tail)) = rest.split_first() {
+        let length = (header & 0xFFFF) as usize;
+        if tail.len() < length {
+            return Err("truncated 3D command packet");
+        }
+        let (payload, tail) = tail.split_at(length);
+        rest = tail;
+        match header >> 16 {
+            OP_CLEAR if length == 7 => {
+                let (x0, y0, x1, y1) = clamp_rect(target, &payload[..4]);
+                for y in y0..y1 {
+                    let row = y * target.width;
+                    if payload[4] & CLEAR_COLOR != 0 {
+                        target.color[row + x0..row + x1].fill(payload[5]);
+                    }
+                    if payload[4] & CLEAR_DEPTH != 0 {
+                        target.depth[row + x0..row + x1].fill(f32::from_bits(payload[6]));
+                    }
+                }
+            }
+            OP_STATE if length == 4 => {
+                context.state =
+                    State { flags: payload[0], depth_func: payload[1], src_blend: payload[2], dest_blend: payload[3] };
+            }
+            OP_SCISSOR if length == 4 => context.scissor = clamp_rect(target, payload),
+            OP_DRAW if length >= 1 => {
+                let vertices: Vec<Vertex> = payload[1..]
+                    .chunks_exact(VERTEX_DWORDS)
+                    .map(|v| Vertex {
+                        x: f32::from_bits(v[0]),
+                        y: f32::from_bits(v[1]),
+                        z: f32::from_bits(v[2]),
+                        color: v[3],
+                    })
+                    .collect();
+                match payload[0] {
+                    1 => vertices.iter().for_each(|v| point(target, &context, v)),
+                    2 => vertices.chunks_exact(2).for_each(|v| line(target, &context, &v[0], &v[1])),
+                    3 => vertices.chunks_exact(3).for_each(|v| triangle(target, &context, &v[0], &v[1], &v[2])),
+                    _ => return Err("unknown primitive"),
+                }
+            }
+            _ => return Err("bad 3D command packet"),
+        }
+    }
+    Ok(())
+}
+
+fn clamp_rect(target: &RenderTarget, rect: &[u32]) -> (usize, usize, usize, usize) {
+    let x1 = (rect[2] as usize).min(target.width);
+    let y1 = (rect[3] as usize).min(target.height);
+    ((rect[0] as usize).min(x1), (rect[1] as usize).min(y1), x1, y1)
+}
+
+fn point(target: &mut RenderTarget, context: &Context, v: &Vertex) {
+    if v.x >= 0.0 && v.y >= 0.0 {
+        plot(target, context, v.x as usize, v.y as usize, v.z, unpack(v.color));
+    }
+}
+
+// Longest line drawn, past any screen; longer ones are cut short rather
+// than stepped for ever
+const MAX_LINE_STEPS: usize = 1 << 14;
+
+fn line(target: &mut RenderTarget, context: &Context, a: &Vertex, b: &Vertex) {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let steps = (dx.max(-dx).max(dy).max(-dy) as usize + 1).min(MAX_LINE_STEPS);
+    let (ca, cb) = (unpack(a.color), unpack(b.color));
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let (x, y) = (a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t);
+        if x >= 0.0 && y >= 0.0 {
+            let color = core::array::from_fn(|i| ca[i] + (cb[i] - ca[i]) * t);
+            plot(target, context, x as usize, y as usize, a.z + (b.z - a.z) * t, color);
+        }
+    }
+}
+
+// Pixels whose centers are inside, with the top-left fill rule so that
+// triangles sharing an edge draw each pixel on it once; color and depth
+// are interpolated across the screen
+fn triangle(target: &mut RenderTarget, context: &Context, a: &Vertex, b: &Vertex, c: &Vertex) {
+    let area = edge(a, b, c.x, c.y);
+    if area == 0.0 || !area.is_finite() {
+        return;
+    }
+    // Wind the triangle one way, so inside is where every edge is positive
+    let (b, c) = if area < 0.0 { (c, b) } else { (b, c) };
+    let area = area.max(-area);
+
+    let (x0, y0, x1, y1) = context.scissor;
+    // Bounds clamped to the scissor first, so the casts do not wrap
+    let clamp = |v: f32, low: usize, high: usize| v.max(low as f32).min(high as f32) as usize;
+    let left = clamp(a.x.min(b.x).min(c.x), x0, x1);
+    let right = (clamp(a.x.max(b.x).max(c.x), x0, x1) + 1).min(x1);
+    let top = clamp(a.y.min(b.y).min(c.y), y0, y1);
+    let bottom = (clamp(a.y.max(b.y).max(c.y), y0, y1) + 1).min(y1);
+    let colors = [unpack(a.color), unpack(b.color), unpack(c.color)];
+
+    for y in top..bottom {
+        let py = y as f32 + 0.5;
+        for x in left..right {
+            let px = x as f32 + 0.5;
+            let weights = [edge(b, c, px, py), edge(c, a, px, py), edge(a, b, px, py)];
+            let edges = [(b, c), (c, a), (a, b)];
+            let inside = weights.iter().zip(edges).all(|(&w, (from, to))| w > 0.0 || (w == 0.0 && top_left(from, to)));
+            if !inside {
+                continue;
+            }
+            let [wa, wb, wc] = weights.map(|w| w / area);
+            let z = a.z * wa + b.z * wb + c.z * wc;
+            let color = core::array::from_fn(|i| colors[0][i] * wa + colors[1][i] * wb + colors[2][i] * wc);
+            plot(target, context, x, y, z, color);
+        }
+    }
+}
+
+// Twice the signed area of (from, to, (x, y)); positive when the point is
+// to the right of the edge as y grows down
+fn edge(from: &Vertex, to: &Vertex, x: f32, y: f32) -> f32 {
+    (to.x - from.x) * (y - from.y) - (to.y - from.y) * (x - from.x)
+}
+
+fn top_left(from: &Vertex, to: &Vertex) -> bool {
+    (from.y == to.y && to.x < from.x) || to.y > from.y
+}
+
+fn plot(target: &mut RenderTarget, context: &Context, x: usize, y: usize, z: f32, color: [f32; 4]) {
+    let (x0, y0, x1, y1) = context.scissor;
+    if x < x0 || x >= x1 || y < y0 || y >= y1 {
+        return;
+    }
+    fragment(target, &context.state, y * target.width + x, z, color);
+}
+
+/// Depth test, blend and write one pixel's color as `state` says
+pub(crate) fn fragment(target: &mut RenderTarget, state: &State, index: usize, z: f32, color: [f32; 4]) {
+    if state.flags & STATE_DEPTH_TEST != 0 {
+        if !compare(state.depth_func, z, target.depth[index]) {
+            return;
+        }
+        if state.flags & STATE_DEPTH_WRITE != 0 {
+            target.depth[index] = z;
+        }
+    }
+    let color = if state.flags & STATE_BLEND != 0 {
+        let dest = unpack(target.color[index]);
+        let src_factor = factor(state.src_blend, &color, &dest);
+        let dest_factor = factor(state.dest_blend, &color, &dest);
+        core::array::from_fn(|i| color[i] * src_factor[i] + dest[i] * dest_factor[i])
+    } else {
+        color
+    };
+    target.color[index] = pack(color);
+}
+
+pub(crate) fn compare(func: u32, z: f32, stored: f32) -> bool {
+    match func {
+        CMP_NEVER => false,
+        CMP_LESS => z < stored,
+        CMP_EQUAL => z == stored,
+        CMP_LESS_EQUAL => z <= stored,
+        CMP_GREATER => z > stored,
+        CMP_NOT_EQUAL => z != stored,
+        CMP_GREATER_EQUAL => z >= stored,
+        _ => true,
+    }
+}
+
+fn factor(blend: u32, src: &[f32; 4], dest: &[f32; 4]) -> [f32; 4] {
+    match blend {
+        BLEND_ZERO => [0.0; 4],
+        BLEND_SRC_COLOR => *src,
+        BLEND_INV_SRC_COLOR => src.map(|c| 1.0 - c),
+        BLEND_SRC_ALPHA => [src[0]; 4],
+        BLEND_INV_SRC_ALPHA => [1.0 - src[0]; 4],
+        BLEND_DEST_ALPHA => [dest[0]; 4],
+        BLEND_INV_DEST_ALPHA => [1.0 - dest[0]; 4],
+        BLEND_DEST_COLOR => *dest,
+        BLEND_INV_DEST_COLOR => dest.map(|c| 1.0 - c),
+        _ => [1.0; 4],
+    }
+}
+
+/// An ARGB color as [a, r, g, b] from 0 to 1
+pub fn unpack(color: u32) -> [f32; 4] {
+    [24, 16, 8, 0].map(|shift| ((color >> shift) & 0xFF) as f32 / 255.0)
+}
+
+/// [a, r, g, b] from 0 to 1 as an ARGB color, clamped
+pub fn pack(color: [f32; 4]) -> u32 {
+    color.iter().fold(0, |packed, c| packed << 8 | (c.clamp(0.0, 1.0) * 255.0 + 0.5) as u32)
+}
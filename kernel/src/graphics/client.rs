@@ -0,0 +1,483 @@
+//! Windows for user processes
+//!
+//! CreateWindow gives a process a descriptor for a framed desktop window
+//! whose client area is the size asked for. Mapped with MAP_SHARED, the
+//! descriptor is the window's surface: `width * height` pixels of
+//! 0x00RRGGBB, a row at a time, which the process draws into directly.
+//! DrawWindow puts a rectangle of the surface, or all of it when the
+//! rectangle is null, on the screen.
+//!
+//! HandleEvent takes the window's next [`Event`], and fails with EAGAIN
+//! when none is waiting, as nothing here blocks. Each window queues 256,
+//! dropping the oldest when full; pointer moves in a row are merged into
+//! the last. Pointer positions are relative to the client area.
+//!
+//! A click focuses a window and raises it, and dragging its title bar
+//! moves it. A window holds the pointer from a press in its client area
+//! until every button is up. What is typed goes to the focused window, by
+//! way of `console::set_sink`; clicking the desktop gives it back to the
+//! shell. The first window brings up the graphics subsystem, and from then
+//! on the desktop is composed from the main loop.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+
+use super::framebuffer::FramebufferOps;
+use super::window::{Window, WindowFlags, WindowHitTest, WindowId, WINDOW_MANAGER};
+use super::{compositor, Point};
+use crate::drivers::mouse::{MousePacket, MOUSE_DRIVER};
+use crate::input::console;
+use crate::memory::page_cache::{self, FileKey};
+use crate::memory::userspace::validate_user_buffer;
+use crate::sync::Mutex;
+use crate::syscall::{EAGAIN, EBADF, EFAULT, EINVAL, EIO, EMFILE, ENODEV};
+
+/// The pointer moved
+pub const EVENT_POINTER_MOVE: u32 = 1;
+/// A button was pressed: `code` is which
+pub const EVENT_BUTTON_DOWN: u32 = 2;
+/// A button was let go: `code` is which
+pub const EVENT_BUTTON_UP: u32 = 3;
+/// The wheel turned: `code` is how far, signed, as the mouse reports it
+pub const EVENT_WHEEL: u32 = 4;
+/// A character was typed: `code` is the Unicode scalar value
+pub const EVENT_CHAR: u32 = 5;
+pub const EVENT_FOCUS_IN: u32 = 6;
+pub const EVENT_FOCUS_OUT: u32 = 7;
+/// The close button was clicked; the window stays until the process
+/// closes it
+pub const EVENT_CLOSE: u32 = 8;
+
+pub const BUTTON_LEFT: u32 = 1;
+pub const BUTTON_RIGHT: u32 = 2;
+pub const BUTTON_MIDDLE: u32 = 3;
+
+// Largest client area, either way
+const MAX_SIZE: u32 = 4096;
+
+/// Longest title, in bytes
+pub const MAX_TITLE: usize = 128;
+
+// Events a window holds before it drops them
+const QUEUE_LENGTH: usize = 256;
+
+// Per-process limit on windows
+const MAX_WINDOWS: usize = 16;
+
+// Clear of the serial ports' from 512 and streams' from 768
+const FIRST_FD: usize = 576;
+
+// Where a window goes when the process leaves the position to us
+const WINDOW_ORIGIN: (i32, i32) = (48, 48);
+
+const TITLE: &str = "Untitled";
+
+/// An event, as HandleEvent writes it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub kind: u32,
+    pub code: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Event {
+    fn new(kind: u32, code: u32) -> Self {
+        Self { kind, code, x: 0, y: 0 }
+    }
+
+    fn at(kind: u32, code: u32, (x, y): (i32, i32)) -> Self {
+        Self { kind, code, x, y }
+    }
+}
+
+/// The part of the surface DrawWindow puts on the screen
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Damage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// A window's key, as (process, fd)
+type Owner = (u32, usize);
+
+struct Client {
+    window: WindowId,
+    surface: FileKey,
+    width: u32,
+    height: u32,
+    events: VecDeque<Event>,
+}
+
+impl Client {
+    fn push(&mut self, event: Event) {
+        if event.kind == EVENT_POINTER_MOVE {
+            if let Some(last) = self.events.back_mut().filter(|last| last.kind == EVENT_POINTER_MOVE) {
+                *last = event;
+                return;
+            }
+        }
+        if self.events.len() == QUEUE_LENGTH {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn whole(&self) -> Damage {
+        Damage { x: 0, y: 0, width: self.width, height: self.height }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        page_cache::release_anonymous(&self.surface);
+        if let Some(manager) = WINDOW_MANAGER.lock().as_mut() {
+            manager.destroy_window(self.window);
+        }
+        REDRAW.store(true, Ordering::Relaxed);
+    }
+}
+
+// What the pointer is doing
+struct Pointer {
+    // Held, as left, right and middle from bit 0
+    buttons: u8,
+    // The window a press in its client area gave the pointer to
+    grab: Option<Owner>,
+    // The window whose title bar is being dragged, and where in it
+    drag: Option<(WindowId, i32, i32)>,
+    // The window typing goes to
+    focus: Option<Owner>,
+}
+
+// Window by (process, fd)
+static CLIENTS: Mutex<BTreeMap<Owner, Client>> = Mutex::new(BTreeMap::new());
+// Taken before CLIENTS where both are held, and both before the window
+// manager
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer { buttons: 0, grab: None, drag: None, focus: None });
+// The desktop needs composing again
+static REDRAW: AtomicBool = AtomicBool::new(false);
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+/// Whether `fd` is a window the caller has open
+pub fn owns(fd: usize) -> bool {
+    CLIENTS.lock().contains_key(&(current_pid(), fd))
+}
+
+/// The surface of one of the caller's windows, for mmap
+pub fn mappable(fd: usize) -> Option<FileKey> {
+    CLIENTS.lock().get(&(current_pid(), fd)).map(|client| client.surface.clone())
+}
+
+/// Open a window at `x`, `y` whose client area is `width` by `height`;
+/// a position of `usize::MAX` leaves it to us
+pub fn create(x: usize, y: usize, width: usize, height: usize, title: Option<String>) -> Result<usize, usize> {
+    let width = u32::try_from(width).ok().filter(|width| (1..=MAX_SIZE).contains(width)).ok_or(EINVAL)?;
+    let height = u32::try_from(height).ok().filter(|height| (1..=MAX_SIZE).contains(height)).ok_or(EINVAL)?;
+    let (x, y) = match (i32::try_from(x), i32::try_from(y)) {
+        (Ok(x), Ok(y)) => (x, y),
+        _ => WINDOW_ORIGIN,
+    };
+    let pid = current_pid();
+
+    let mut pointer = POINTER.lock();
+    let mut clients = CLIENTS.lock();
+    let used: Vec<usize> = clients.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_WINDOWS {
+        return Err(EMFILE);
+    }
+    if WINDOW_MANAGER.lock().is_none() {
+        super::init().map_err(|_| ENODEV)?;
+    }
+    let window = {
+        let mut manager = WINDOW_MANAGER.lock();
+        let manager = manager.as_mut().ok_or(ENODEV)?;
+        let flags = WindowFlags::VISIBLE
+            | WindowFlags::MOVABLE
+            | WindowFlags::CLOSABLE
+            | WindowFlags::HAS_TITLE_BAR
+            | WindowFlags::HAS_BORDER;
+        let title = title.filter(|title| !title.is_empty()).unwrap_or_else(|| String::from(TITLE));
+        let id = manager.create_window_with_params(title, x, y, width, height, flags);
+        if let Some(window) = manager.get_window_mut(id) {
+            fit(window, width, height);
+        }
+        id
+    };
+
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    let surface = page_cache::create_anonymous(u64::from(width) * u64::from(height) * 4);
+    clients.insert((pid, fd), Client { window, surface, width, height, events: VecDeque::new() });
+    focus(&mut pointer, &mut clients, Some((pid, fd)));
+    Ok(fd)
+}
+
+// Size a window so its client area is `width` by `height`, and draw its
+// frame
+fn fit(window: &mut Window, width: u32, height: u32) {
+    let extra_width = window.rect.width - window.client_rect.width;
+    let extra_height = window.rect.height - window.client_rect.height;
+    window.resize(width + extra_width, height + extra_height);
+    window.paint();
+}
+
+// Forget the windows `owners` as the pointer's and the keyboard's
+fn forget(owners: &[Owner]) {
+    let mut pointer = POINTER.lock();
+    if pointer.grab.is_some_and(|grab| owners.contains(&grab)) {
+        pointer.grab = None;
+    }
+    if pointer.focus.is_some_and(|focus| owners.contains(&focus)) {
+        pointer.focus = None;
+        console::set_sink(None);
+    }
+}
+
+/// Close a window; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let owner = (current_pid(), fd);
+    if CLIENTS.lock().remove(&owner).is_none() {
+        return false;
+    }
+    forget(&[owner]);
+    true
+}
+
+/// Close every window an exiting process has open
+pub fn release_process(pid: u32) {
+    let owners: Vec<Owner> = {
+        let mut clients = CLIENTS.lock();
+        let owners: Vec<Owner> = clients.range((pid, 0)..=(pid, usize::MAX)).map(|(&owner, _)| owner).collect();
+        owners.iter().for_each(|owner| drop(clients.remove(owner)));
+        owners
+    };
+    if !owners.is_empty() {
+        forget(&owners);
+    }
+}
+
+/// Put the rectangle of the surface at `damage`, or all of it when null,
+/// on the screen
+pub fn draw(fd: usize, damage: usize) -> Result<usize, usize> {
+    let damage = if damage == 0 {
+        None
+    } else {
+        let addr = VirtAddr::try_new(damage as u64).map_err(|_| EFAULT)?;
+        if !validate_user_buffer(addr, size_of::<Damage>()) {
+            return Err(EFAULT);
+        }
+        Some(unsafe { core::ptr::read_unaligned(damage as *const Damage) })
+    };
+    let clients = CLIENTS.lock();
+    let client = clients.get(&(current_pid(), fd)).ok_or(EBADF)?;
+    present(client, damage.unwrap_or_else(|| client.whole()))?;
+    REDRAW.store(true, Ordering::Relaxed);
+    Ok(0)
+}
+
+// Copy the part of a client's surface in `damage` into its window, or the
+// whole surface when the window's frame needs painting, which clears it
+fn present(client: &Client, damage: Damage) -> Result<(), usize> {
+    let mut manager = WINDOW_MANAGER.lock();
+    let window = manager.as_mut().and_then(|manager| manager.get_window_mut(client.window)).ok_or(EBADF)?;
+    let damage = if window.is_dirty {
+        window.paint();
+        client.whole()
+    } else {
+        damage
+    };
+
+    let left = (window.client_rect.x - window.rect.x).max(0) as usize;
+    let top = (window.client_rect.y - window.rect.y).max(0) as usize;
+    let pitch = window.framebuffer.width();
+    let width = (client.width as usize).min(pitch.saturating_sub(left));
+    let height = (client.height as usize).min(window.framebuffer.height().saturating_sub(top));
+    let x0 = (damage.x as usize).min(width);
+    let y0 = (damage.y as usize).min(height);
+    let x1 = x0.saturating_add(damage.width as usize).min(width);
+    let y1 = y0.saturating_add(damage.height as usize).min(height);
+    if x0 == x1 {
+        return Ok(());
+    }
+
+    let mut row = vec![0u8; (x1 - x0) * 4];
+    let pixels = window.framebuffer.buffer_mut();
+    for y in y0..y1 {
+        let offset = (y * client.width as usize + x0) * 4;
+        page_cache::read(&client.surface, offset as u64, &mut row).map_err(|_| EIO)?;
+        let to = &mut pixels[(top + y) * pitch + left + x0..][..x1 - x0];
+        for (to, from) in to.iter_mut().zip(row.chunks_exact(4)) {
+            *to = u32::from_le_bytes([from[0], from[1], from[2], from[3]]) | 0xFF00_0000;
+        }
+    }
+    Ok(())
+}
+
+/// Take the next event for a window into `event`
+pub fn next_event(fd: usize, event: usize) -> Result<usize, usize> {
+    let addr = VirtAddr::try_new(event as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, size_of::<Event>()) {
+        return Err(EFAULT);
+    }
+    let next = CLIENTS.lock().get_mut(&(current_pid(), fd)).ok_or(EBADF)?.events.pop_front().ok_or(EAGAIN)?;
+    unsafe { core::ptr::write_unaligned(event as *mut Event, next) };
+    Ok(0)
+}
+
+// Move the keyboard to `owner`, or back to the shell
+fn focus(pointer: &mut Pointer, clients: &mut BTreeMap<Owner, Client>, owner: Option<Owner>) {
+    if pointer.focus == owner {
+        return;
+    }
+    if let Some(old) = pointer.focus.and_then(|old| clients.get_mut(&old)) {
+        old.push(Event::new(EVENT_FOCUS_OUT, 0));
+    }
+    if let Some(new) = owner.and_then(|new| clients.get_mut(&new)) {
+        new.push(Event::new(EVENT_FOCUS_IN, 0));
+        if let Some(manager) = WINDOW_MANAGER.lock().as_mut() {
+            manager.focus_window(new.window);
+        }
+    }
+    pointer.focus = owner;
+    console::set_sink(owner.map(|_| typed as console::Sink));
+    REDRAW.store(true, Ordering::Relaxed);
+}
+
+// The console's sink while a window has the keyboard
+fn typed(text: &str) {
+    let focus = POINTER.lock().focus;
+    let mut clients = CLIENTS.lock();
+    if let Some(client) = focus.and_then(|focus| clients.get_mut(&focus)) {
+        text.chars().for_each(|c| client.push(Event::new(EVENT_CHAR, c as u32)));
+    }
+}
+
+fn owner_of(clients: &BTreeMap<Owner, Client>, window: WindowId) -> Option<Owner> {
+    clients.iter().find(|(_, client)| client.window == window).map(|(&owner, _)| owner)
+}
+
+// `at` relative to a client's client area
+fn local(client: &Client, at: Point) -> (i32, i32) {
+    let manager = WINDOW_MANAGER.lock();
+    let origin = manager.as_ref().and_then(|manager| manager.get_window(client.window)).map(|window| window.client_rect);
+    origin.map_or((at.x, at.y), |rect| (at.x - rect.x, at.y - rect.y))
+}
+
+// Hand a pointer packet to the windows
+fn route(packet: MousePacket) {
+    let at = Point::new(packet.x.into(), packet.y.into());
+    let buttons = u8::from(packet.left_button) | u8::from(packet.right_button) << 1 | u8::from(packet.middle_button) << 2;
+    let mut pointer = POINTER.lock();
+    let mut clients = CLIENTS.lock();
+    let pressed = buttons & !pointer.buttons;
+    let released = pointer.buttons & !buttons;
+    pointer.buttons = buttons;
+
+    if let Some((window, dx, dy)) = pointer.drag {
+        if let Some(window) = WINDOW_MANAGER.lock().as_mut().and_then(|manager| manager.get_window_mut(window)) {
+            window.move_to(at.x - dx, at.y - dy);
+        } else {
+            pointer.drag = None;
+        }
+        if buttons & 1 == 0 {
+            pointer.drag = None;
+        }
+        REDRAW.store(true, Ordering::Relaxed);
+        return;
+    }
+
+    let under = {
+        let manager = WINDOW_MANAGER.lock();
+        let manager = manager.as_ref();
+        manager.and_then(|manager| {
+            let window = manager.get_window(manager.window_at_point(at)?)?;
+            Some((window.id, window.hit_test(at), window.rect))
+        })
+    };
+    let target = under.and_then(|(window, _, _)| owner_of(&clients, window));
+
+    if pressed != 0 {
+        focus(&mut pointer, &mut clients, target);
+        match under {
+            Some((_, WindowHitTest::CloseButton, _)) => {
+                if let Some(client) = target.and_then(|target| clients.get_mut(&target)) {
+                    client.push(Event::new(EVENT_CLOSE, 0));
+                }
+            }
+            Some((window, WindowHitTest::TitleBar, rect)) if pressed & 1 != 0 && target.is_some() => {
+                pointer.drag = Some((window, at.x - rect.x, at.y - rect.y));
+            }
+            Some((_, WindowHitTest::Client, _)) => pointer.grab = pointer.grab.or(target),
+            _ => {}
+        }
+    }
+
+    // The window holding the pointer has it wherever it goes
+    let in_client = matches!(under, Some((_, WindowHitTest::Client, _)));
+    let receiver = pointer.grab.or(target.filter(|_| in_client));
+    if let Some(client) = receiver.and_then(|receiver| clients.get_mut(&receiver)) {
+        let position = local(client, at);
+        if packet.dx != 0 || packet.dy != 0 {
+            client.push(Event::at(EVENT_POINTER_MOVE, 0, position));
+        }
+        for bit in 0..3 {
+            if pressed & 1 << bit != 0 && pointer.grab == receiver {
+                client.push(Event::at(EVENT_BUTTON_DOWN, bit + 1, position));
+            }
+            if released & 1 << bit != 0 {
+                client.push(Event::at(EVENT_BUTTON_UP, bit + 1, position));
+            }
+        }
+        if packet.z_delta != 0 {
+            client.push(Event::at(EVENT_WHEEL, i32::from(packet.z_delta) as u32, position));
+        }
+    }
+    if buttons == 0 {
+        pointer.grab = None;
+    }
+}
+
+// Paint the frames of windows the window manager has marked dirty, which
+// clears their client areas, and copy their surfaces back in
+fn repaint() {
+    let clients = CLIENTS.lock();
+    for client in clients.values() {
+        let dirty = WINDOW_MANAGER.lock().as_ref().and_then(|manager| manager.get_window(client.window)).is_some_and(|window| window.is_dirty);
+        if dirty {
+            let _ = present(client, client.whole());
+        }
+    }
+}
+
+/// Hand the pointer's packets to the windows and compose the desktop if
+/// anything changed; called from the main loop
+pub fn poll() {
+    if WINDOW_MANAGER.lock().is_none() {
+        return;
+    }
+    let mut last = None;
+    while let Some(packet) = x86_64::instructions::interrupts::without_interrupts(|| MOUSE_DRIVER.lock().poll_event()) {
+        route(packet);
+        last = Some(packet);
+    }
+    if let Some(packet) = last {
+        compositor::set_pointer(packet.x.into(), packet.y.into());
+    }
+    if REDRAW.swap(false, Ordering::Relaxed) {
+        repaint();
+        compositor::request_redraw();
+    }
+    compositor::compose();
+}
@@ -45,6 +45,8 @@ pub struct Compositor {
     frame_count: u64,
     last_fps_time: u64,
     current_fps: u32,
+    // Where to draw the mouse pointer, once a user window follows it
+    pointer: Option<Point>,
 }
 
 impl Compositor {
@@ -62,6 +64,7 @@ impl Compositor {
             frame_count: 0,
             last_fps_time: 0,
             current_fps: 0,
+            pointer: None,
         }
     }
     
@@ -86,6 +89,10 @@ impl Compositor {
             self.draw_fps();
         }
         
+        if let Some(pointer) = self.pointer {
+            self.draw_pointer(pointer);
+        }
+        
         // Update frame counter
         self.frame_count += 1;
         
@@ -171,6 +178,42 @@ impl Compositor {
         );
     }
     
+    // An arrow, white with a black edge, its tip at `at`
+    fn draw_pointer(&mut self, at: Point) {
+        const ARROW: [&str; 12] = [
+            "X",
+            "XX",
+            "X.X",
+            "X..X",
+            "X...X",
+            "X....X",
+            "X.....X",
+            "X......X",
+            "X....XXXX",
+            "X..X.X",
+            "X.X X.X",
+            "XX   XX",
+        ];
+        for (row, line) in ARROW.iter().enumerate() {
+            for (column, cell) in line.chars().enumerate() {
+                let color = match cell {
+                    'X' => Color::BLACK,
+                    '.' => Color::WHITE,
+                    _ => continue,
+                };
+                let (x, y) = (at.x + column as i32, at.y + row as i32);
+                if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+                    self.screen_buffer.set_pixel(x as usize, y as usize, color);
+                }
+            }
+        }
+    }
+    
+    pub fn set_pointer(&mut self, x: i32, y: i32) {
+        self.pointer = Some(Point::new(x, y));
+        self.needs_redraw = true;
+    }
+    
     fn draw_fps(&mut self) {
         let fps_text = alloc::format!("FPS: {}", self.current_fps);
         
@@ -206,6 +249,11 @@ impl Compositor {
         self.needs_redraw = true;
     }
     
+    // What was last composed, for those showing the screen elsewhere
+    pub fn screen(&self) -> &Framebuffer {
+        &self.screen_buffer
+    }
+    
     pub fn set_show_fps(&mut self, show: bool) {
         self.show_fps = show;
         self.needs_redraw = true;
@@ -246,6 +294,12 @@ pub fn request_redraw() {
     }
 }
 
+pub fn set_pointer(x: i32, y: i32) {
+    if let Some(compositor) = COMPOSITOR.lock().as_mut() {
+        compositor.set_pointer(x, y);
+    }
+}
+
 pub fn set_show_fps(show: bool) {
     if let Some(compositor) = COMPOSITOR.lock().as_mut() {
         compositor.set_show_fps(show);
@@ -165,12 +165,14 @@ impl FontRenderer {
     
     // Draw a single character
     pub fn draw_char(&self, fb: &mut dyn FramebufferOps, c: char, x: usize, y: usize, color: Color) {
-        let char_index = c as usize;
-        if char_index >= 128 {
-            return; // Only ASCII supported for now
-        }
-        
-        let char_data = &self.font_data[char_index];
+        // Characters past ASCII are drawn through their fallbacks
+        let char_data = match self.font_data.get(c as usize) {
+            Some(char_data) => char_data,
+            None => match glyph(c) {
+                Some(char_data) => char_data,
+                None => return,
+            },
+        };
         
         for row in 0..self.char_height {
             let row_data = char_data[row];
@@ -219,7 +221,7 @@ impl FontRenderer {
     pub fn measure_text(&self, text: &str) -> (usize, usize) {
         let lines: Vec<&str> = text.split('\n').collect();
         let max_width = lines.iter()
-            .map(|line| line.len() * self.char_width)
+            .map(|line| line.chars().count() * self.char_width)
             .max()
             .unwrap_or(0);
         
@@ -286,4 +288,44 @@ pub fn draw_text_with_bg(
 
 pub fn measure_text(text: &str) -> (usize, usize) {
     get_renderer().measure_text(text)
+}
+
+// Characters the font lacks, each drawn as the character at the same
+// place in FALLBACK_TO: accented Latin letters without their accents,
+// typographic punctuation and box-drawing lines
+const FALLBACK_FROM: &str = concat!(
+    "ÀÁÂÃÄÅÆÇÈÉÊËÌÍÎÏÐÑÒÓÔÕÖ×ØÙÚÛÜÝÞßàáâãäåæçèéêëìíîï",
+    "ðñòóôõö÷øùúûüýþÿĀāĂăĄąĆćĈĉĊċČčĎďĐđĒēĔĕĖėĘęĚěĜĝĞğ",
+    "ĠġĢģĤĥĦħĨĩĪīĬĭĮįİıĲĳĴĵĶķĸĹĺĻļĽľĿŀŁłŃńŅņŇňŉŊŋŌōŎŏ",
+    "ŐőŒœŔŕŖŗŘřŚśŜŝŞşŠšŢţŤťŦŧŨũŪūŬŭŮůŰűŲųŴŵŶŷŸŹźŻżŽžſ",
+    "‘’‚“”„–—…«»¡¿\u{A0}│─┌┐└┘├┤┬┴┼·•°€£¥©®µ¦¨´¸¬±",
+);
+const FALLBACK_TO: &str = concat!(
+    "AAAAAAACEEEEIIIIDNOOOOOxOUUUUYPsaaaaaaaceeeeiiii",
+    "dnooooo/ouuuuypyAaAaAaCcCcCcCcDdDdEeEeEeEeEeGgGg",
+    "GgGgHhHhIiIiIiIiIiJjJjKkkLlLlLlLlLlNnNnNnnNnOoOo",
+    "OoOoRrRrRrSsSsSsSsTtTtTtUuUuUuUuUuUuWwYyYZzZzZzs",
+    "'',\"\"\"--.\"\"!? |-+++++++++.*oELYCRu|\"',-+",
+);
+
+// Drawn for a character with neither a glyph nor a fallback
+const MISSING: [u8; 8] = [0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x00];
+
+/// The ASCII character drawn for `c`, which the font lacks
+pub fn fallback(c: char) -> Option<char> {
+    let index = FALLBACK_FROM.chars().position(|from| from == c)?;
+    FALLBACK_TO.chars().nth(index)
+}
+
+/// The 8x8 bitmap drawn for a character, a byte per row with bit 0 the
+/// leftmost pixel: an ASCII character's own, another's through
+/// `fallback`, or else a box. None for the C1 control characters.
+pub fn glyph(c: char) -> Option<&'static [u8; 8]> {
+    if let Some(bitmap) = SIMPLE_FONT.get(c as usize) {
+        return Some(bitmap);
+    }
+    if c.is_control() {
+        return None;
+    }
+    Some(fallback(c).and_then(|c| SIMPLE_FONT.get(c as usize)).unwrap_or(&MISSING))
 }
\ No newline at end of file
@@ -0,0 +1,299 @@
+//! Synopsys DesignWare I2C controllers, as in Intel's LPSS
+//!
+//! Laptops since Skylake put their touchpad and touchscreen on one of these
+//! PCI functions. The controller is polled: commands go into its transmit
+//! FIFO, a read being a command too, and bytes come back through the
+//! receive FIFO. The bus runs at 400 kHz with the SCL timing the firmware
+//! left, or timing worked out from the controller's input clock when it
+//! left none.
+
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER};
+use crate::time::clocksource::now_ns;
+
+use super::I2cAdapter;
+
+const IC_CON: usize = 0x00;
+const IC_TAR: usize = 0x04;
+const IC_DATA_CMD: usize = 0x10;
+const IC_FS_SCL_HCNT: usize = 0x1C;
+const IC_FS_SCL_LCNT: usize = 0x20;
+const IC_INTR_MASK: usize = 0x30;
+const IC_RAW_INTR_STAT: usize = 0x34;
+const IC_CLR_INTR: usize = 0x40;
+const IC_CLR_TX_ABRT: usize = 0x54;
+const IC_CLR_STOP_DET: usize = 0x60;
+const IC_ENABLE: usize = 0x6C;
+const IC_STATUS: usize = 0x70;
+const IC_TXFLR: usize = 0x74;
+const IC_RXFLR: usize = 0x78;
+const IC_TX_ABRT_SOURCE: usize = 0x80;
+const IC_ENABLE_STATUS: usize = 0x9C;
+const IC_COMP_PARAM_1: usize = 0xF4;
+const IC_COMP_TYPE: usize = 0xFC;
+
+// LPSS private registers, after the controller's own
+const LPSS_RESETS: usize = 0x204;
+// Out of reset: the function and its DMA engine
+const LPSS_RESETS_RELEASE: u32 = 0x7;
+
+const COMP_TYPE_DESIGNWARE: u32 = 0x4457_0140;
+
+// IC_CON: master, fast mode, repeated starts, slave off
+const CON_MASTER: u32 = 1 << 0;
+const CON_SPEED_FAST: u32 = 2 << 1;
+const CON_RESTART_EN: u32 = 1 << 5;
+const CON_SLAVE_DISABLE: u32 = 1 << 6;
+
+// IC_DATA_CMD
+const CMD_READ: u32 = 1 << 8;
+const CMD_STOP: u32 = 1 << 9;
+const CMD_RESTART: u32 = 1 << 10;
+
+// IC_RAW_INTR_STAT
+const INTR_TX_ABRT: u32 = 1 << 6;
+const INTR_STOP_DET: u32 = 1 << 9;
+
+const STATUS_ACTIVITY: u32 = 1 << 0;
+// IC_TX_ABRT_SOURCE: the address went unacknowledged
+const ABRT_7BIT_ADDR_NOACK: u32 = 1 << 0;
+
+const SPEED_HZ: u32 = 400_000;
+// Fast mode minimum SCL high and low times and the fall time, in ns
+const FAST_HIGH_NS: u32 = 600;
+const FAST_LOW_NS: u32 = 1300;
+const FALL_NS: u32 = 300;
+
+const TIMEOUT_MS: u64 = 50;
+
+const INTEL: u16 = 0x8086;
+
+// Intel LPSS I2C functions and their input clock in kHz
+const CONTROLLERS: [(u16, u32); 38] = [
+    // Sunrise Point-LP and -H, and Kaby Lake-H
+    (0x9D60, 120_000),
+    (0x9D61, 120_000),
+    (0x9D62, 120_000),
+    (0x9D63, 120_000),
+    (0x9D64, 120_000),
+    (0x9D65, 120_000),
+    (0xA160, 120_000),
+    (0xA161, 120_000),
+    (0xA2E0, 120_000),
+    (0xA2E1, 120_000),
+    (0xA2E2, 120_000),
+    (0xA2E3, 120_000),
+    // Cannon Lake-LP and -H
+    (0x9DE8, 216_000),
+    (0x9DE9, 216_000),
+    (0x9DEA, 216_000),
+    (0x9DEB, 216_000),
+    (0xA368, 216_000),
+    (0xA369, 216_000),
+    (0xA36A, 216_000),
+    (0xA36B, 216_000),
+    // Comet Lake-LP
+    (0x02E8, 216_000),
+    (0x02E9, 216_000),
+    (0x02EA, 216_000),
+    (0x02EB, 216_000),
+    // Ice Lake-LP
+    (0x34E8, 216_000),
+    (0x34E9, 216_000),
+    (0x34EA, 216_000),
+    (0x34EB, 216_000),
+    // Tiger Lake-LP
+    (0xA0E8, 216_000),
+    (0xA0E9, 216_000),
+    (0xA0EA, 216_000),
+    (0xA0EB, 216_000),
+    // Alder Lake-P and -S
+    (0x51E8, 216_000),
+    (0x51E9, 216_000),
+    (0x51EA, 216_000),
+    (0x51EB, 216_000),
+    (0x7ACC, 216_000),
+    (0x7ACD, 216_000),
+];
+
+const fn id_table<const N: usize>(controllers: [(u16, u32); N]) -> [Match; N] {
+    let mut table = [Match::PciId { vendor: INTEL, device: 0 }; N];
+    let mut i = 0;
+    while i < N {
+        table[i] = Match::PciId { vendor: INTEL, device: controllers[i].0 };
+        i += 1;
+    }
+    table
+}
+
+static ID_TABLE: [Match; CONTROLLERS.len()] = id_table(CONTROLLERS);
+
+pub struct DesignWare {
+    base: u64,
+    tx_depth: u32,
+    rx_depth: u32,
+}
+
+impl DesignWare {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { write_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *mut u32, value) }
+    }
+
+    /// Take the controller at `base` out of reset and set it up as master
+    pub fn new(base: u64, clock_khz: u32) -> Result<DesignWare, &'static str> {
+        let mut controller = DesignWare { base, tx_depth: 0, rx_depth: 0 };
+        controller.write(LPSS_RESETS, LPSS_RESETS_RELEASE);
+        if controller.read(IC_COMP_TYPE) != COMP_TYPE_DESIGNWARE {
+            return Err("not a DesignWare controller");
+        }
+        let params = controller.read(IC_COMP_PARAM_1);
+        controller.tx_depth = ((params >> 16) & 0xFF) + 1;
+        controller.rx_depth = ((params >> 8) & 0xFF) + 1;
+
+        controller.set_enabled(false)?;
+        controller.write(IC_CON, CON_MASTER | CON_SPEED_FAST | CON_RESTART_EN | CON_SLAVE_DISABLE);
+        if controller.read(IC_FS_SCL_HCNT) == 0 || controller.read(IC_FS_SCL_LCNT) == 0 {
+            let cycles = |ns: u32| (clock_khz as u64 * ns as u64).div_ceil(1_000_000) as u32;
+            controller.write(IC_FS_SCL_HCNT, cycles(FAST_HIGH_NS + FALL_NS).saturating_sub(3));
+            controller.write(IC_FS_SCL_LCNT, cycles(FAST_LOW_NS + FALL_NS).saturating_sub(1));
+        }
+        controller.write(IC_INTR_MASK, 0);
+        controller.read(IC_CLR_INTR);
+        Ok(controller)
+    }
+
+    fn set_enabled(&self, enabled: bool) -> Result<(), &'static str> {
+        self.write(IC_ENABLE, enabled as u32);
+        let deadline = now_ns() + TIMEOUT_MS * 1_000_000;
+        while self.read(IC_ENABLE_STATUS) & 1 != enabled as u32 {
+            if now_ns() >= deadline {
+                return Err("controller did not switch on or off");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    // Queue the commands and collect what is read, keeping both FIFOs
+    // from overflowing
+    fn transfer(&mut self, write: &[u8], read: &mut [u8]) -> Result<(), &'static str> {
+        let total = write.len() + read.len();
+        let (mut sent, mut received) = (0, 0);
+        let deadline = now_ns() + TIMEOUT_MS * 1_000_000;
+        while sent < total || received < read.len() {
+            while sent < total && self.read(IC_TXFLR) < self.tx_depth {
+                let mut command = match write.get(sent) {
+                    Some(&byte) => byte as u32,
+                    None if sent - write.len() - received >= self.rx_depth as usize => break,
+                    None if sent == write.len() && !write.is_empty() => CMD_READ | CMD_RESTART,
+                    None => CMD_READ,
+                };
+                if sent + 1 == total {
+                    command |= CMD_STOP;
+                }
+                self.write(IC_DATA_CMD, command);
+                sent += 1;
+            }
+            for _ in 0..self.read(IC_RXFLR) {
+                if received < read.len() {
+                    read[received] = self.read(IC_DATA_CMD) as u8;
+                    received += 1;
+                }
+            }
+            if self.read(IC_RAW_INTR_STAT) & INTR_TX_ABRT != 0 {
+                let source = self.read(IC_TX_ABRT_SOURCE);
+                self.read(IC_CLR_TX_ABRT);
+                return Err(if source & ABRT_7BIT_ADDR_NOACK != 0 {
+                    "no device answered"
+                } else {
+                    "transfer aborted"
+                });
+            }
+            if now_ns() >= deadline {
+                return Err("transfer timed out");
+            }
+        }
+        while self.read(IC_RAW_INTR_STAT) & INTR_STOP_DET == 0 {
+            if now_ns() >= deadline {
+                return Err("no stop condition");
+            }
+            core::hint::spin_loop();
+        }
+        self.read(IC_CLR_STOP_DET);
+        Ok(())
+    }
+}
+
+impl I2cAdapter for DesignWare {
+    fn write_read(&mut self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), &'static str> {
+        if write.is_empty() && read.is_empty() {
+            return Err("empty transfer");
+        }
+        if self.read(IC_STATUS) & STATUS_ACTIVITY != 0 {
+            return Err("bus busy");
+        }
+        self.set_enabled(false)?;
+        self.write(IC_TAR, address as u32);
+        self.set_enabled(true)?;
+        self.read(IC_CLR_INTR);
+        let result = self.transfer(write, read);
+        let _ = self.set_enabled(false);
+        result
+    }
+
+    fn speed_hz(&self) -> u32 {
+        SPEED_HZ
+    }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+/// Binds Intel LPSS I2C controllers found on PCI
+pub static PCI_DRIVER: DesignWarePciDriver = DesignWarePciDriver;
+
+pub struct DesignWarePciDriver;
+
+impl Driver for DesignWarePciDriver {
+    fn name(&self) -> &'static str {
+        "i2c_designware"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &ID_TABLE
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, device: id, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let clock_khz = CONTROLLERS.iter().find(|(device, _)| *device == id).map(|&(_, clock)| clock);
+        let clock_khz = clock_khz.ok_or(ProbeError::NoDevice)?;
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        let base = (pci.bars[0] as u64 & !0xF) | ((pci.bars[1] as u64) << 32);
+        if base == 0 {
+            return Err(ProbeError::Failed("no register BAR"));
+        }
+        PCIE_CONTROLLER.lock().enable_device(&pci);
+        let controller = DesignWare::new(base, clock_khz).map_err(ProbeError::Failed)?;
+        super::register(device.id, "designware", Box::new(controller));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        super::unregister(device.id);
+    }
+}
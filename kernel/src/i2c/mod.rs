@@ -0,0 +1,90 @@
+//! I2C buses
+//!
+//! Controller drivers register each bus they bring up, which is named
+//! `i2c-0`, `i2c-1` and so on in the order they come; names are not reused
+//! when a controller goes away. Firmware describes the devices on a bus in
+//! the ACPI namespace, which is not read, so each bus is searched for the
+//! devices there are drivers for as it registers: see `input::i2c_hid`.
+
+pub mod designware;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::driver::DeviceId;
+
+/// A bus controller
+pub trait I2cAdapter: Send {
+    /// Write `write` to the device at the 7-bit `address`, then, unless
+    /// `read` is empty, fill `read` after a repeated start
+    fn write_read(&mut self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), &'static str>;
+
+    /// The clock the bus runs at
+    fn speed_hz(&self) -> u32;
+}
+
+#[derive(Debug, Clone)]
+pub struct BusInfo {
+    pub index: usize,
+    pub driver: &'static str,
+    pub speed_hz: u32,
+}
+
+struct Bus {
+    index: usize,
+    device: DeviceId,
+    driver: &'static str,
+    adapter: Box<dyn I2cAdapter>,
+}
+
+static BUSES: Mutex<Vec<Bus>> = Mutex::new(Vec::new());
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Register a controller's bus and look for devices on it, returning the
+/// bus number
+pub fn register(device: DeviceId, driver: &'static str, adapter: Box<dyn I2cAdapter>) -> usize {
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    crate::serial_println!("i2c-{}: {} at {} kHz", index, driver, adapter.speed_hz() / 1000);
+    BUSES.lock().push(Bus { index, device, driver, adapter });
+    crate::input::i2c_hid::scan(index);
+    index
+}
+
+/// Take away the buses of a controller that has gone
+pub fn unregister(device: DeviceId) {
+    let gone: Vec<usize> = {
+        let mut buses = BUSES.lock();
+        let gone = buses.iter().filter(|bus| bus.device == device).map(|bus| bus.index).collect();
+        buses.retain(|bus| bus.device != device);
+        gone
+    };
+    for index in gone {
+        crate::input::i2c_hid::detach_bus(index);
+    }
+}
+
+/// Write then read, as one transaction with a repeated start between
+pub fn write_read(bus: usize, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), &'static str> {
+    let mut buses = BUSES.lock();
+    let bus = buses.iter_mut().find(|b| b.index == bus).ok_or("no such I2C bus")?;
+    bus.adapter.write_read(address, write, read)
+}
+
+pub fn write(bus: usize, address: u8, data: &[u8]) -> Result<(), &'static str> {
+    write_read(bus, address, data, &mut [])
+}
+
+pub fn read(bus: usize, address: u8, buffer: &mut [u8]) -> Result<(), &'static str> {
+    write_read(bus, address, &[], buffer)
+}
+
+pub fn buses() -> Vec<BusInfo> {
+    BUSES
+        .lock()
+        .iter()
+        .map(|bus| BusInfo { index: bus.index, driver: bus.driver, speed_hz: bus.adapter.speed_hz() })
+        .collect()
+}
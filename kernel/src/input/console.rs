@@ -0,0 +1,157 @@
+//! The console's keyboard
+//!
+//! Keys from keyboards no reader has grabbed are typed at the shell: the
+//! modifiers are followed across every keyboard, and each key going down,
+//! or repeating, is turned into symbols by the layout in `keymap`. A dead
+//! key waits for the next key to put its accent on, and with an input
+//! method on, keys go through it first (see `ime`); Ctrl+Space turns it on
+//! and off. What is typed waits here until the main loop hands it over, so
+//! a shell command never runs under a driver's lock.
+//!
+//! A GUI that wants the text instead of the shell takes it with `set_sink`.
+
+use alloc::string::String;
+
+use spin::Mutex;
+
+use super::ime::{self, Key};
+use super::keymap::{self, Keysym, Modifiers};
+use super::{
+    KEY_BACKSPACE, KEY_CAPSLOCK, KEY_DOWN, KEY_ENTER, KEY_ESC, KEY_KPENTER, KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_RIGHTALT,
+    KEY_RIGHTCTRL, KEY_RIGHTSHIFT, KEY_SPACE, KEY_UP,
+};
+
+/// Takes what the console types, in place of the shell
+pub type Sink = fn(&str);
+
+struct Console {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    altgr: bool,
+    caps_lock: bool,
+    // The accent of a dead key waiting for the next key
+    accent: Option<char>,
+    typed: String,
+    sink: Option<Sink>,
+}
+
+impl Console {
+    fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            altgr: self.altgr,
+            caps_lock: self.caps_lock,
+        }
+    }
+
+    // Type what a key gives, unless the input method takes the key
+    fn type_key(&mut self, code: u16, character: Option<char>, text: &str) {
+        let key = match code {
+            KEY_BACKSPACE => Some(Key::Backspace),
+            KEY_ENTER | KEY_KPENTER => Some(Key::Enter),
+            KEY_ESC => Some(Key::Escape),
+            KEY_SPACE => Some(Key::Space),
+            KEY_UP => Some(Key::Up),
+            KEY_DOWN => Some(Key::Down),
+            _ => character.filter(|c| !c.is_control()).map(Key::Char),
+        };
+        if key.is_some_and(|key| ime::key(key, &mut self.typed)) {
+            return;
+        }
+        self.typed.push_str(text);
+    }
+
+    fn type_char(&mut self, code: u16, character: char) {
+        let mut buffer = [0; 4];
+        self.type_key(code, Some(character), character.encode_utf8(&mut buffer));
+    }
+}
+
+static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    left_shift: false,
+    right_shift: false,
+    left_ctrl: false,
+    right_ctrl: false,
+    altgr: false,
+    caps_lock: false,
+    accent: None,
+    typed: String::new(),
+    sink: None,
+});
+
+/// A key from a keyboard: 1 down, 0 up, 2 repeating
+pub fn key(code: u16, value: i32) {
+    let mut console = CONSOLE.lock();
+    let down = value != 0;
+    match code {
+        KEY_LEFTSHIFT => console.left_shift = down,
+        KEY_RIGHTSHIFT => console.right_shift = down,
+        KEY_LEFTCTRL => console.left_ctrl = down,
+        KEY_RIGHTCTRL => console.right_ctrl = down,
+        KEY_RIGHTALT => console.altgr = down,
+        KEY_CAPSLOCK => console.caps_lock ^= value == 1,
+        KEY_SPACE if console.modifiers().ctrl => {
+            if value == 1 {
+                ime::toggle();
+            }
+        }
+        _ if down => {
+            let Some(symbol) = keymap::current().keysym(code, console.modifiers()) else {
+                return;
+            };
+            match (console.accent.take(), symbol) {
+                (None, Keysym::Dead(accent)) => console.accent = Some(accent),
+                // A second dead key, or Space, types the accent itself
+                (Some(accent), Keysym::Dead(_) | Keysym::Terminal(" ")) => console.typed.push(accent),
+                (accent, Keysym::Char(base)) => match accent.map(|accent| (accent, keymap::compose(accent, base))) {
+                    Some((_, Some(composed))) => console.type_char(code, composed),
+                    Some((accent, None)) => {
+                        console.typed.push(accent);
+                        console.type_char(code, base);
+                    }
+                    None => console.type_char(code, base),
+                },
+                (accent, Keysym::Terminal(sequence)) => {
+                    console.typed.extend(accent);
+                    console.type_key(code, None, sequence);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Forget the modifiers held, whose release a grab may have kept from
+/// the console, and any dead key waiting
+pub fn reset() {
+    let mut console = CONSOLE.lock();
+    console.left_shift = false;
+    console.right_shift = false;
+    console.left_ctrl = false;
+    console.right_ctrl = false;
+    console.altgr = false;
+    console.accent = None;
+}
+
+/// Send what is typed to `sink`, or back to the shell when None
+pub fn set_sink(sink: Option<Sink>) {
+    CONSOLE.lock().sink = sink;
+}
+
+/// Hand what was typed to the shell, or the sink, and show what the input
+/// method is composing; called from `input::poll`
+pub fn poll() {
+    let (typed, sink) = {
+        let mut console = CONSOLE.lock();
+        (core::mem::take(&mut console.typed), console.sink)
+    };
+    ime::update_ui();
+    match sink {
+        Some(sink) if !typed.is_empty() => sink(&typed),
+        Some(_) => {}
+        None => typed.chars().for_each(crate::cmd_shell::handle_keyboard_input),
+    }
+}
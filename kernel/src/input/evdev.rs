@@ -0,0 +1,249 @@
+//! /dev/input/event* character devices
+//!
+//! Each input device has a node, `/dev/input/event<n>` with `n` its
+//! number, as on Linux. A handle on one reads the device's events from the
+//! time it was opened, as `struct input_event`: a timeval, then the type,
+//! code and value, 24 bytes in all. Every handle has its own queue of 256;
+//! one that fills is emptied and given a SYN_DROPPED, as evdev does, so
+//! the reader knows to skip to the next SYN_REPORT.
+//!
+//! EVIOCGRAB takes a device for one handle: its events then go to that
+//! handle alone, not to the console, other handles or the shared queue,
+//! until the handle lets go or is closed or its process exits. This is how
+//! a compositor keeps what is typed in its windows from the shell.
+//!
+//! Reads never block: they fail with EAGAIN when nothing is waiting, and
+//! with ENODEV once a device that has gone has nothing left.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::{InputEvent, EV_SYN, SYN_DROPPED};
+use crate::memory::userspace::validate_user_buffer;
+use crate::sync::Mutex;
+use crate::syscall::{EAGAIN, EBADF, EBUSY, EFAULT, EINVAL, EMFILE, ENODEV, ENOENT, ENOTTY};
+
+pub const DEVICE_PREFIX: &str = "/dev/input/event";
+
+// Linux's requests, from <linux/input.h>
+pub const EVIOCGVERSION: usize = 0x8004_4501;
+pub const EVIOCGREP: usize = 0x8008_4503;
+pub const EVIOCSREP: usize = 0x4008_4503;
+/// EVIOCGNAME(len) is this with the length in bits 16 to 29
+pub const EVIOCGNAME: usize = 0x8000_4506;
+pub const EVIOCGRAB: usize = 0x4004_4590;
+
+const EV_VERSION: i32 = 0x01_0001;
+const EVENT_SIZE: usize = 24;
+const LENGTH_MASK: usize = 0x3FFF << 16;
+
+// Events a handle holds before it drops them
+const QUEUE_LENGTH: usize = 256;
+
+// Per-process limit on open nodes
+const MAX_HANDLES: usize = 32;
+
+// Clear of io_uring's from 384 and the serial ports' from 512
+const FIRST_FD: usize = 448;
+
+// A handle's key, as (process, fd)
+type Owner = (u32, usize);
+
+struct Handle {
+    device: usize,
+    queue: VecDeque<InputEvent>,
+}
+
+// Handle by (process, fd)
+static HANDLES: Mutex<BTreeMap<Owner, Handle>> = Mutex::new(BTreeMap::new());
+// The handle that has grabbed each grabbed device. Taken before HANDLES
+// where both are held.
+static GRABS: Mutex<BTreeMap<usize, Owner>> = Mutex::new(BTreeMap::new());
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+fn device_of(fd: usize) -> Result<usize, usize> {
+    HANDLES.lock().get(&(current_pid(), fd)).map(|handle| handle.device).ok_or(EBADF)
+}
+
+/// Whether `fd` is a node the caller has open
+pub fn owns(fd: usize) -> bool {
+    HANDLES.lock().contains_key(&(current_pid(), fd))
+}
+
+/// Open `/dev/input/event<n>`; None if `path` is not an input node
+pub fn open(path: &str) -> Option<Result<usize, usize>> {
+    path.strip_prefix(DEVICE_PREFIX).map(open_index)
+}
+
+fn open_index(index: &str) -> Result<usize, usize> {
+    let device: usize = index.parse().map_err(|_| ENOENT)?;
+    if !super::devices().get(device).is_some_and(|info| info.present) {
+        return Err(ENODEV);
+    }
+    let pid = current_pid();
+
+    let mut handles = HANDLES.lock();
+    let used: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_HANDLES {
+        return Err(EMFILE);
+    }
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    handles.insert((pid, fd), Handle { device, queue: VecDeque::new() });
+    Ok(fd)
+}
+
+// Let go of the grabs the handles `owners` hold
+fn release_grabs(owners: &[Owner]) {
+    let mut grabs = GRABS.lock();
+    let before = grabs.len();
+    grabs.retain(|_, owner| !owners.contains(owner));
+    if grabs.len() != before {
+        super::console::reset();
+    }
+}
+
+/// Close a handle; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let owner = (current_pid(), fd);
+    if HANDLES.lock().remove(&owner).is_none() {
+        return false;
+    }
+    release_grabs(&[owner]);
+    true
+}
+
+/// Close every node an exiting process has open
+pub fn release_process(pid: u32) {
+    let owners: Vec<Owner> = {
+        let mut handles = HANDLES.lock();
+        let owners: Vec<Owner> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&owner, _)| owner).collect();
+        owners.iter().for_each(|owner| drop(handles.remove(owner)));
+        owners
+    };
+    release_grabs(&owners);
+}
+
+/// Queue an event for the handles open on its device; true if a handle
+/// has grabbed the device, and so the event goes nowhere else
+pub(super) fn deliver(event: &InputEvent) -> bool {
+    let grab = GRABS.lock().get(&event.device).copied();
+    let mut handles = HANDLES.lock();
+    for (owner, handle) in handles.iter_mut() {
+        if handle.device != event.device || grab.is_some_and(|grab| grab != *owner) {
+            continue;
+        }
+        if handle.queue.len() == QUEUE_LENGTH {
+            handle.queue.clear();
+            handle.queue.push_back(InputEvent { kind: EV_SYN, code: SYN_DROPPED, value: 0, ..*event });
+        }
+        handle.queue.push_back(*event);
+    }
+    grab.is_some()
+}
+
+/// The process whose handle has grabbed `device`, if one has
+pub fn grabbed_by(device: usize) -> Option<u32> {
+    GRABS.lock().get(&device).map(|&(pid, _)| pid)
+}
+
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, usize> {
+    if buffer.len() < EVENT_SIZE {
+        return Err(EINVAL);
+    }
+    let (device, events) = {
+        let mut handles = HANDLES.lock();
+        let handle = handles.get_mut(&(current_pid(), fd)).ok_or(EBADF)?;
+        let count = handle.queue.len().min(buffer.len() / EVENT_SIZE);
+        (handle.device, handle.queue.drain(..count).collect::<Vec<InputEvent>>())
+    };
+    if events.is_empty() {
+        let present = super::devices().get(device).is_some_and(|info| info.present);
+        return Err(if present { EAGAIN } else { ENODEV });
+    }
+
+    for (event, out) in events.iter().zip(buffer.chunks_exact_mut(EVENT_SIZE)) {
+        let seconds = (event.time_ns / 1_000_000_000) as i64;
+        let micros = (event.time_ns % 1_000_000_000 / 1000) as i64;
+        out[0..8].copy_from_slice(&seconds.to_le_bytes());
+        out[8..16].copy_from_slice(&micros.to_le_bytes());
+        out[16..18].copy_from_slice(&event.kind.to_le_bytes());
+        out[18..20].copy_from_slice(&event.code.to_le_bytes());
+        out[20..24].copy_from_slice(&event.value.to_le_bytes());
+    }
+    Ok(events.len() * EVENT_SIZE)
+}
+
+fn write_user<T: Copy>(addr: usize, value: &T) -> Result<(), usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    unsafe { (addr as *mut T).write_unaligned(*value) };
+    Ok(())
+}
+
+fn read_user<T: Copy>(addr: usize) -> Result<T, usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { (addr as *const T).read_unaligned() })
+}
+
+// Take or let go of the handle's device
+fn grab(owner: Owner, device: usize, take: bool) -> Result<(), usize> {
+    let mut grabs = GRABS.lock();
+    match (grabs.get(&device), take) {
+        (None, true) => {
+            grabs.insert(device, owner);
+        }
+        (Some(&holder), false) if holder == owner => {
+            grabs.remove(&device);
+            drop(grabs);
+            super::console::reset();
+        }
+        (Some(_), true) => return Err(EBUSY),
+        _ => return Err(EINVAL),
+    }
+    Ok(())
+}
+
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, usize> {
+    let device = device_of(fd)?;
+    match request {
+        EVIOCGVERSION => write_user(arg, &EV_VERSION)?,
+        // Repeat is the same for every keyboard
+        EVIOCGREP => {
+            let (delay, period) = super::repeat_rate();
+            write_user(arg, &[delay, period])?;
+        }
+        EVIOCSREP => {
+            let [delay, period]: [u32; 2] = read_user(arg)?;
+            super::set_repeat_rate(delay, period);
+        }
+        EVIOCGRAB => grab((current_pid(), fd), device, arg != 0)?,
+        _ if request & !LENGTH_MASK == EVIOCGNAME => {
+            let length = (request & LENGTH_MASK) >> 16;
+            let info = super::devices().into_iter().nth(device).ok_or(ENODEV)?;
+            let name = info.name.as_bytes();
+            let count = (name.len() + 1).min(length);
+            let addr = VirtAddr::try_new(arg as u64).map_err(|_| EFAULT)?;
+            if !validate_user_buffer(addr, count) {
+                return Err(EFAULT);
+            }
+            let out = unsafe { core::slice::from_raw_parts_mut(arg as *mut u8, count) };
+            let copied = name.len().min(count);
+            out[..copied].copy_from_slice(&name[..copied]);
+            if count > copied {
+                out[copied] = 0;
+            }
+            return Ok(count);
+        }
+        _ => return Err(ENOTTY),
+    }
+    Ok(0)
+}
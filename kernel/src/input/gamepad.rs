@@ -0,0 +1,255 @@
+//! Gamepads
+//!
+//! Every pad reports in one layout, XInput's: sixteen button bits, two
+//! triggers from 0 to 255 and two sticks from -32768 to 32767, up and
+//! right positive. Drivers turn their own reports into a `State`, so a
+//! DualShock's Cross and an Xbox pad's A are the same button here.
+//!
+//! A pad takes the first of four player slots free when it connects, and
+//! gives it back when it is unplugged. It is also an input device of kind
+//! gamepad, whose events use Linux's gamepad codes: sticks on ABS_X, ABS_Y,
+//! ABS_RX and ABS_RY with down positive, triggers on ABS_Z and ABS_RZ, and
+//! the d-pad on the first hat. Rumble goes back to the pad through the
+//! callback its driver gave, and a timed one is stopped from `poll`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{
+    DeviceKind, ABS_HAT0X, ABS_HAT0Y, ABS_RX, ABS_RY, ABS_RZ, ABS_X, ABS_Y, ABS_Z, BTN_EAST, BTN_MODE, BTN_NORTH,
+    BTN_SELECT, BTN_SOUTH, BTN_START, BTN_THUMBL, BTN_THUMBR, BTN_TL, BTN_TR, BTN_WEST, EV_ABS, EV_KEY,
+};
+use crate::serial_println;
+
+/// Player slots, as XInput has
+pub const MAX_PADS: usize = 4;
+
+// Buttons, as XINPUT_GAMEPAD's wButtons
+pub const DPAD_UP: u16 = 0x0001;
+pub const DPAD_DOWN: u16 = 0x0002;
+pub const DPAD_LEFT: u16 = 0x0004;
+pub const DPAD_RIGHT: u16 = 0x0008;
+pub const START: u16 = 0x0010;
+pub const BACK: u16 = 0x0020;
+pub const LEFT_THUMB: u16 = 0x0040;
+pub const RIGHT_THUMB: u16 = 0x0080;
+pub const LEFT_SHOULDER: u16 = 0x0100;
+pub const RIGHT_SHOULDER: u16 = 0x0200;
+/// The Xbox or PS button, which XInputGetState does not report
+pub const GUIDE: u16 = 0x0400;
+pub const A: u16 = 0x1000;
+pub const B: u16 = 0x2000;
+pub const X: u16 = 0x4000;
+pub const Y: u16 = 0x8000;
+
+// The buttons that are keys on the input device
+const KEYS: [(u16, u16); 11] = [
+    (A, BTN_SOUTH),
+    (B, BTN_EAST),
+    (X, BTN_WEST),
+    (Y, BTN_NORTH),
+    (LEFT_SHOULDER, BTN_TL),
+    (RIGHT_SHOULDER, BTN_TR),
+    (BACK, BTN_SELECT),
+    (START, BTN_START),
+    (GUIDE, BTN_MODE),
+    (LEFT_THUMB, BTN_THUMBL),
+    (RIGHT_THUMB, BTN_THUMBR),
+];
+
+/// Buttons and axes at one moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct State {
+    pub buttons: u16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub thumb_lx: i16,
+    pub thumb_ly: i16,
+    pub thumb_rx: i16,
+    pub thumb_ry: i16,
+}
+
+impl State {
+    // The d-pad as a hat: -1, 0 or 1 across and down
+    fn hat(&self) -> (i32, i32) {
+        let axis = |minus: u16, plus: u16| (self.buttons & plus != 0) as i32 - (self.buttons & minus != 0) as i32;
+        (axis(DPAD_LEFT, DPAD_RIGHT), axis(DPAD_UP, DPAD_DOWN))
+    }
+
+    // The axes as the input device reports them
+    fn axes(&self) -> [(u16, i32); 8] {
+        let (hat_x, hat_y) = self.hat();
+        [
+            (ABS_X, self.thumb_lx as i32),
+            (ABS_Y, !self.thumb_ly as i32),
+            (ABS_RX, self.thumb_rx as i32),
+            (ABS_RY, !self.thumb_ry as i32),
+            (ABS_Z, self.left_trigger as i32),
+            (ABS_RZ, self.right_trigger as i32),
+            (ABS_HAT0X, hat_x),
+            (ABS_HAT0Y, hat_y),
+        ]
+    }
+}
+
+/// Set the motors' speeds, the low-frequency one first
+pub type Rumble = Arc<dyn Fn(u16, u16) -> Result<(), &'static str> + Send + Sync>;
+
+struct Pad {
+    name: String,
+    input: usize,
+    /// Bumped whenever the state changes, as XInput's dwPacketNumber
+    packet: u32,
+    state: State,
+    vibration: (u16, u16),
+    rumble: Option<Rumble>,
+    /// When a timed rumble stops
+    rumble_until: Option<u64>,
+}
+
+static PADS: Mutex<BTreeMap<usize, Pad>> = Mutex::new(BTreeMap::new());
+
+/// A pad, for listing
+#[derive(Debug, Clone)]
+pub struct PadInfo {
+    pub slot: usize,
+    pub name: String,
+    pub input: usize,
+    pub packet: u32,
+    pub vibration: (u16, u16),
+    pub can_rumble: bool,
+}
+
+/// Give a pad a player slot, or None if all four are taken
+pub fn connect(name: &str, rumble: Option<Rumble>) -> Option<usize> {
+    let mut pads = PADS.lock();
+    let slot = (0..MAX_PADS).find(|slot| !pads.contains_key(slot))?;
+    let input = super::register(name, DeviceKind::Gamepad);
+    let pad = Pad {
+        name: String::from(name),
+        input,
+        packet: 0,
+        state: State::default(),
+        vibration: (0, 0),
+        rumble,
+        rumble_until: None,
+    };
+    pads.insert(slot, pad);
+    serial_println!("gamepad: {} connected as player {}", name, slot + 1);
+    Some(slot)
+}
+
+/// Let a slot go, once its pad is unplugged
+pub fn disconnect(slot: usize) {
+    if let Some(pad) = PADS.lock().remove(&slot) {
+        super::unregister(pad.input);
+        serial_println!("gamepad: {} disconnected from player {}", pad.name, slot + 1);
+    }
+}
+
+/// A pad's new state, from its latest report
+pub fn update(slot: usize, state: State) {
+    let (input, old) = {
+        let mut pads = PADS.lock();
+        let Some(pad) = pads.get_mut(&slot) else {
+            return;
+        };
+        if pad.state == state {
+            return;
+        }
+        let old = pad.state;
+        pad.state = state;
+        pad.packet = pad.packet.wrapping_add(1);
+        (pad.input, old)
+    };
+    for ((code, value), (_, was)) in state.axes().into_iter().zip(old.axes()) {
+        if value != was {
+            super::report(input, EV_ABS, code, value);
+        }
+    }
+    for (bit, code) in KEYS {
+        if (state.buttons ^ old.buttons) & bit != 0 {
+            super::key(input, code, state.buttons & bit != 0);
+        }
+    }
+    super::sync(input);
+}
+
+/// The packet number and state of the pad in a slot
+pub fn state(slot: usize) -> Option<(u32, State)> {
+    PADS.lock().get(&slot).map(|pad| (pad.packet, pad.state))
+}
+
+pub fn connected(slot: usize) -> bool {
+    PADS.lock().contains_key(&slot)
+}
+
+/// The motors' speeds last set
+pub fn vibration(slot: usize) -> Option<(u16, u16)> {
+    PADS.lock().get(&slot).map(|pad| pad.vibration)
+}
+
+/// Whether the pad has motors its driver can drive
+pub fn can_rumble(slot: usize) -> bool {
+    PADS.lock().get(&slot).is_some_and(|pad| pad.rumble.is_some())
+}
+
+/// Set the motors' speeds, low-frequency then high, until set again
+pub fn set_vibration(slot: usize, low: u16, high: u16) -> Result<(), &'static str> {
+    start(slot, low, high, None)
+}
+
+/// Run the motors for `ms` milliseconds
+pub fn rumble_for(slot: usize, low: u16, high: u16, ms: u64) -> Result<(), &'static str> {
+    start(slot, low, high, Some(crate::time::clocksource::now_ns() + ms * 1_000_000))
+}
+
+fn start(slot: usize, low: u16, high: u16, until: Option<u64>) -> Result<(), &'static str> {
+    let rumble = {
+        let mut pads = PADS.lock();
+        let pad = pads.get_mut(&slot).ok_or("no pad in that slot")?;
+        pad.vibration = (low, high);
+        pad.rumble_until = until;
+        pad.rumble.clone()
+    };
+    // The driver is called with no lock held, as it may submit transfers
+    match rumble {
+        Some(rumble) => rumble(low, high),
+        None if low == 0 && high == 0 => Ok(()),
+        None => Err("pad has no motors"),
+    }
+}
+
+pub fn pads() -> Vec<PadInfo> {
+    PADS.lock()
+        .iter()
+        .map(|(&slot, pad)| PadInfo {
+            slot,
+            name: pad.name.clone(),
+            input: pad.input,
+            packet: pad.packet,
+            vibration: pad.vibration,
+            can_rumble: pad.rumble.is_some(),
+        })
+        .collect()
+}
+
+/// Stop timed rumbles that are due; called from `input::poll`
+pub fn poll() {
+    let now = crate::time::clocksource::now_ns();
+    let due: Vec<usize> = PADS
+        .lock()
+        .iter()
+        .filter(|(_, pad)| pad.rumble_until.is_some_and(|until| now >= until))
+        .map(|(&slot, _)| slot)
+        .collect();
+    for slot in due {
+        if let Err(e) = set_vibration(slot, 0, 0) {
+            serial_println!("gamepad: cannot stop player {}'s rumble: {}", slot + 1, e);
+        }
+    }
+}
@@ -0,0 +1,706 @@
+//! HID reports: the report descriptor, and what touchpads, mice and
+//! consumer keys put in their reports
+//!
+//! The descriptor is read into fields: where each sits in which report,
+//! its usages, and the collections around it. A precision touchpad's
+//! report holds one logical collection per finger, with a contact ID, a tip
+//! switch and X and Y in each, and a contact count; reports in hybrid mode
+//! carry a few fingers each and only the first gives the count. Touchpads
+//! start out as mice and become touchpads when their Input Mode feature is
+//! set to 3.
+//!
+//! A touchpad's frames become multitouch events, and one finger moving
+//! moves the pointer as a mouse would; two moving up or down scroll.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{
+    ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_MT_TRACKING_ID, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE,
+    BTN_RIGHT, BTN_TOOL_DOUBLETAP, BTN_TOOL_FINGER, BTN_TOUCH, EV_ABS, KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP,
+    KEY_MUTE, KEY_RFKILL, KEY_SLEEP, KEY_VOLUMEDOWN, KEY_VOLUMEUP,
+};
+
+// Item types and the tags of each
+const MAIN: u8 = 0;
+const GLOBAL: u8 = 1;
+const LOCAL: u8 = 2;
+const LONG_ITEM: u8 = 0xFE;
+
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xA;
+const MAIN_FEATURE: u8 = 0xB;
+const MAIN_END_COLLECTION: u8 = 0xC;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_LOGICAL_MIN: u8 = 0x1;
+const GLOBAL_LOGICAL_MAX: u8 = 0x2;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xA;
+const GLOBAL_POP: u8 = 0xB;
+
+const LOCAL_USAGE: u8 = 0x0;
+const LOCAL_USAGE_MIN: u8 = 0x1;
+const LOCAL_USAGE_MAX: u8 = 0x2;
+
+const COLLECTION_APPLICATION: u32 = 1;
+
+// Main item flags
+const FLAG_CONSTANT: u32 = 1 << 0;
+const FLAG_VARIABLE: u32 = 1 << 1;
+const FLAG_RELATIVE: u32 = 1 << 2;
+
+// Usages, as page << 16 | ID
+const GD_POINTER: u32 = 0x0001_0001;
+const GD_MOUSE: u32 = 0x0001_0002;
+const GD_X: u32 = 0x0001_0030;
+const GD_Y: u32 = 0x0001_0031;
+const GD_WHEEL: u32 = 0x0001_0038;
+const GD_SYSTEM_SLEEP: u32 = 0x0001_0082;
+const GD_WIRELESS_RADIO_BUTTON: u32 = 0x0001_00C6;
+const PAGE_BUTTON: u32 = 0x0009;
+const PAGE_CONSUMER: u32 = 0x000C;
+const CONSUMER_BRIGHTNESS_UP: u32 = 0x000C_006F;
+const CONSUMER_BRIGHTNESS_DOWN: u32 = 0x000C_0070;
+const CONSUMER_MUTE: u32 = 0x000C_00E2;
+const CONSUMER_VOLUME_UP: u32 = 0x000C_00E9;
+const CONSUMER_VOLUME_DOWN: u32 = 0x000C_00EA;
+const DIGITIZER_TOUCH_PAD: u32 = 0x000D_0005;
+const DIGITIZER_CONFIGURATION: u32 = 0x000D_000E;
+const DIGITIZER_FINGER: u32 = 0x000D_0022;
+const DIGITIZER_TIP_SWITCH: u32 = 0x000D_0042;
+const DIGITIZER_CONFIDENCE: u32 = 0x000D_0047;
+const DIGITIZER_CONTACT_ID: u32 = 0x000D_0051;
+const DIGITIZER_INPUT_MODE: u32 = 0x000D_0052;
+const DIGITIZER_CONTACT_COUNT: u32 = 0x000D_0054;
+const DIGITIZER_SURFACE_SWITCH: u32 = 0x000D_0057;
+const DIGITIZER_BUTTON_SWITCH: u32 = 0x000D_0058;
+
+const INPUT_MODE_TOUCHPAD: u32 = 3;
+
+// Usages a range may expand to, so a bad descriptor cannot exhaust memory
+const MAX_USAGES: usize = 1024;
+// Collections and pushed globals a descriptor may nest
+const MAX_DEPTH: usize = 16;
+
+// Pointer speed: the pad's width crosses this many pixels
+const PAD_WIDTH_PIXELS: i32 = 1200;
+// Pad units of two-finger travel per wheel click, as a share of its height
+const SCROLL_STEP_DIVISOR: i32 = 20;
+
+/// Key codes for the usages that are keys
+const KEYS: [(u32, u16); 7] = [
+    (CONSUMER_MUTE, KEY_MUTE),
+    (CONSUMER_VOLUME_DOWN, KEY_VOLUMEDOWN),
+    (CONSUMER_VOLUME_UP, KEY_VOLUMEUP),
+    (CONSUMER_BRIGHTNESS_DOWN, KEY_BRIGHTNESSDOWN),
+    (CONSUMER_BRIGHTNESS_UP, KEY_BRIGHTNESSUP),
+    (GD_SYSTEM_SLEEP, KEY_SLEEP),
+    (GD_WIRELESS_RADIO_BUTTON, KEY_RFKILL),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportKind {
+    Input,
+    Output,
+    Feature,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub kind: ReportKind,
+    pub report_id: u8,
+    /// Bit offset after the report ID
+    pub offset: usize,
+    pub size: usize,
+    pub count: usize,
+    /// One per value for variable fields; the values' meanings for arrays
+    pub usages: Vec<u32>,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    pub flags: u32,
+    /// The top-level collection's usage
+    pub application: u32,
+    /// Which finger collection the field is in, numbered in order
+    pub finger: Option<usize>,
+}
+
+impl Field {
+    /// Whether each value is a usage's value, rather than an index into the
+    /// usages
+    pub fn variable(&self) -> bool {
+        self.flags & FLAG_VARIABLE != 0
+    }
+
+    /// The usage of value `index`, for variable fields
+    pub fn usage(&self, index: usize) -> u32 {
+        self.usages.get(index).or(self.usages.last()).copied().unwrap_or(0)
+    }
+
+    /// Value `index` of the field in a report's data
+    pub fn value(&self, data: &[u8], index: usize) -> i32 {
+        let offset = self.offset + index * self.size;
+        let size = self.size.min(32);
+        let mut value = 0u32;
+        for bit in 0..size {
+            let at = offset + bit;
+            if data.get(at / 8).is_some_and(|byte| byte >> (at % 8) & 1 != 0) {
+                value |= 1 << bit;
+            }
+        }
+        if self.logical_min < 0 && (1..32).contains(&size) && value & (1 << (size - 1)) != 0 {
+            value |= !((1u32 << size) - 1);
+        }
+        value as i32
+    }
+
+    fn set(&self, data: &mut [u8], index: usize, value: u32) {
+        let offset = self.offset + index * self.size;
+        for bit in 0..self.size.min(32) {
+            let at = offset + bit;
+            if let Some(byte) = data.get_mut(at / 8) {
+                if value >> bit & 1 != 0 {
+                    *byte |= 1 << (at % 8);
+                } else {
+                    *byte &= !(1 << (at % 8));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Globals {
+    usage_page: u32,
+    logical_min: i32,
+    logical_max: i32,
+    report_size: usize,
+    report_id: u8,
+    report_count: usize,
+}
+
+/// A parsed report descriptor
+#[derive(Debug, Clone, Default)]
+pub struct ReportDescriptor {
+    pub fields: Vec<Field>,
+    /// Reports start with their ID
+    pub numbered: bool,
+}
+
+impl ReportDescriptor {
+    pub fn parse(data: &[u8]) -> Result<ReportDescriptor, &'static str> {
+        let mut descriptor = ReportDescriptor::default();
+        let mut globals = Globals::default();
+        let mut stack: Vec<Globals> = Vec::new();
+        let mut usages: Vec<u32> = Vec::new();
+        let mut usage_min = None;
+        // Open collections: their usage and whether each is a finger
+        let mut collections: Vec<(u32, Option<usize>)> = Vec::new();
+        let mut fingers = 0;
+        // Bits so far in each report
+        let mut lengths: Vec<(ReportKind, u8, usize)> = Vec::new();
+
+        let mut at = 0;
+        while at < data.len() {
+            let prefix = data[at];
+            if prefix == LONG_ITEM {
+                let size = *data.get(at + 1).ok_or("truncated long item")? as usize;
+                at += 3 + size;
+                continue;
+            }
+            let size = match prefix & 3 {
+                3 => 4,
+                size => size as usize,
+            };
+            let bytes = data.get(at + 1..at + 1 + size).ok_or("truncated item")?;
+            at += 1 + size;
+            let unsigned = bytes.iter().rev().fold(0u32, |value, &byte| value << 8 | byte as u32);
+            let signed = match size {
+                1 => bytes[0] as i8 as i32,
+                2 => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+                _ => unsigned as i32,
+            };
+            let (kind, tag) = ((prefix >> 2) & 3, prefix >> 4);
+            // A usage's page is the current one unless the item gives it
+            let page = globals.usage_page;
+            let full_usage = |value: u32| if size == 4 { value } else { page << 16 | value };
+
+            match (kind, tag) {
+                (MAIN, MAIN_COLLECTION) => {
+                    if collections.len() == MAX_DEPTH {
+                        return Err("collections nested too deep");
+                    }
+                    let usage = usages.first().copied().unwrap_or(0);
+                    let finger = if usage == DIGITIZER_FINGER {
+                        fingers += 1;
+                        Some(fingers - 1)
+                    } else {
+                        collections.last().and_then(|&(_, finger)| finger)
+                    };
+                    // Each application is a device of its own: number
+                    // its fingers afresh
+                    if unsigned == COLLECTION_APPLICATION && collections.is_empty() {
+                        fingers = 0;
+                    }
+                    collections.push((usage, finger));
+                }
+                (MAIN, MAIN_END_COLLECTION) => {
+                    collections.pop();
+                }
+                (MAIN, MAIN_INPUT | MAIN_OUTPUT | MAIN_FEATURE) => {
+                    let kind = match tag {
+                        MAIN_INPUT => ReportKind::Input,
+                        MAIN_OUTPUT => ReportKind::Output,
+                        _ => ReportKind::Feature,
+                    };
+                    let length = match lengths.iter_mut().find(|(k, id, _)| *k == kind && *id == globals.report_id) {
+                        Some((_, _, length)) => length,
+                        None => {
+                            lengths.push((kind, globals.report_id, 0));
+                            &mut lengths.last_mut().unwrap().2
+                        }
+                    };
+                    let offset = *length;
+                    *length += globals.report_size * globals.report_count;
+                    if unsigned & FLAG_CONSTANT == 0 && globals.report_size > 0 {
+                        let mut logical_max = globals.logical_max;
+                        // A maximum written without room for its sign
+                        if logical_max < globals.logical_min {
+                            logical_max = globals.logical_max & 0xFFFF;
+                        }
+                        descriptor.fields.push(Field {
+                            kind,
+                            report_id: globals.report_id,
+                            offset,
+                            size: globals.report_size,
+                            count: globals.report_count,
+                            usages: core::mem::take(&mut usages),
+                            logical_min: globals.logical_min,
+                            logical_max,
+                            flags: unsigned,
+                            application: collections.first().map_or(0, |&(usage, _)| usage),
+                            finger: collections.last().and_then(|&(_, finger)| finger),
+                        });
+                    }
+                }
+                (GLOBAL, GLOBAL_USAGE_PAGE) => globals.usage_page = unsigned & 0xFFFF,
+                (GLOBAL, GLOBAL_LOGICAL_MIN) => globals.logical_min = signed,
+                (GLOBAL, GLOBAL_LOGICAL_MAX) => globals.logical_max = signed,
+                (GLOBAL, GLOBAL_REPORT_SIZE) => globals.report_size = unsigned as usize,
+                (GLOBAL, GLOBAL_REPORT_COUNT) => globals.report_count = unsigned as usize,
+                (GLOBAL, GLOBAL_REPORT_ID) => {
+                    globals.report_id = unsigned as u8;
+                    descriptor.numbered = true;
+                }
+                (GLOBAL, GLOBAL_PUSH) if stack.len() < MAX_DEPTH => stack.push(globals),
+                (GLOBAL, GLOBAL_POP) => globals = stack.pop().ok_or("pop without push")?,
+                (LOCAL, LOCAL_USAGE) if usages.len() < MAX_USAGES => usages.push(full_usage(unsigned)),
+                (LOCAL, LOCAL_USAGE_MIN) => usage_min = Some(full_usage(unsigned)),
+                (LOCAL, LOCAL_USAGE_MAX) => {
+                    let max = full_usage(unsigned);
+                    let min = usage_min.take().unwrap_or(max);
+                    usages.extend((min..=max).take(MAX_USAGES.saturating_sub(usages.len())));
+                }
+                _ => {}
+            }
+            // Local items last until the next main item
+            if kind == MAIN {
+                usages.clear();
+                usage_min = None;
+            }
+        }
+        Ok(descriptor)
+    }
+
+    /// The first field of `kind` with `usage` among its usages
+    pub fn find(&self, kind: ReportKind, usage: u32) -> Option<&Field> {
+        self.fields.iter().find(|field| field.kind == kind && field.usages.contains(&usage))
+    }
+
+    /// Bytes in report `id` of `kind`, without the ID
+    pub fn report_length(&self, kind: ReportKind, id: u8) -> usize {
+        let bits = self
+            .fields
+            .iter()
+            .filter(|field| field.kind == kind && field.report_id == id)
+            .map(|field| field.offset + field.size * field.count)
+            .max()
+            .unwrap_or(0);
+        bits.div_ceil(8)
+    }
+
+    pub fn has_application(&self, usage: u32) -> bool {
+        self.fields.iter().any(|field| field.application == usage)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Contact {
+    id: i32,
+    tip: bool,
+    confident: bool,
+    x: i32,
+    y: i32,
+}
+
+// A touchpad's axes, from its first finger
+#[derive(Debug, Clone, Copy)]
+struct Axes {
+    x_min: i32,
+    x_max: i32,
+    y_min: i32,
+    y_max: i32,
+}
+
+#[derive(Debug, Default)]
+struct Touch {
+    // Contacts of the frame being gathered, and how many it will have
+    gathering: Vec<Contact>,
+    expected: usize,
+    // Slots: the contact ID in each, and where it was last frame
+    slots: Vec<Option<Contact>>,
+    next_tracking_id: i32,
+    tracking_ids: Vec<i32>,
+    button: bool,
+    scroll: i32,
+}
+
+/// What the descriptor makes of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Touchpad,
+    Mouse,
+    Keys,
+}
+
+/// A HID device's reports turned into input events
+pub struct HidInput {
+    pub descriptor: ReportDescriptor,
+    pub device: usize,
+    pub role: Role,
+    axes: Option<Axes>,
+    touch: Touch,
+    // Keys down, with the report each came in
+    keys_down: Vec<(u8, u16)>,
+    mouse_buttons: u8,
+}
+
+impl HidInput {
+    pub fn new(descriptor: ReportDescriptor, device: usize) -> HidInput {
+        let x = descriptor.find(ReportKind::Input, GD_X);
+        let y = descriptor.find(ReportKind::Input, GD_Y);
+        let axes = match (x, y) {
+            (Some(x), Some(y)) if x.finger.is_some() => Some(Axes {
+                x_min: x.logical_min,
+                x_max: x.logical_max.max(x.logical_min + 1),
+                y_min: y.logical_min,
+                y_max: y.logical_max.max(y.logical_min + 1),
+            }),
+            _ => None,
+        };
+        let role = if axes.is_some() && descriptor.has_application(DIGITIZER_TOUCH_PAD) {
+            Role::Touchpad
+        } else if descriptor.has_application(GD_MOUSE) || descriptor.has_application(GD_POINTER) {
+            Role::Mouse
+        } else {
+            Role::Keys
+        };
+        HidInput { descriptor, device, role, axes, touch: Touch::default(), keys_down: Vec::new(), mouse_buttons: 0 }
+    }
+
+    /// The feature report that turns a touchpad from mouse to touchpad
+    /// mode, with the surface and button reporting on, as its ID and data
+    pub fn input_mode_report(&self) -> Option<(u8, Vec<u8>)> {
+        let mode = self.descriptor.find(ReportKind::Feature, DIGITIZER_INPUT_MODE)?;
+        if mode.application != DIGITIZER_CONFIGURATION {
+            return None;
+        }
+        let mut data = vec![0u8; self.descriptor.report_length(ReportKind::Feature, mode.report_id)];
+        let fields = self.descriptor.fields.iter();
+        for field in fields.filter(|f| f.kind == ReportKind::Feature && f.report_id == mode.report_id) {
+            for index in 0..field.count {
+                match field.usage(index) {
+                    DIGITIZER_INPUT_MODE => field.set(&mut data, index, INPUT_MODE_TOUCHPAD),
+                    DIGITIZER_SURFACE_SWITCH | DIGITIZER_BUTTON_SWITCH => field.set(&mut data, index, 1),
+                    _ => {}
+                }
+            }
+        }
+        Some((mode.report_id, data))
+    }
+
+    /// Act on an input report, its ID first if the device numbers them
+    pub fn report(&mut self, report: &[u8]) {
+        let (id, data) = match (self.descriptor.numbered, report.split_first()) {
+            (true, Some((&id, data))) => (id, data),
+            (true, None) => return,
+            (false, _) => (0, report),
+        };
+        self.keys(id, data);
+        match self.role {
+            Role::Touchpad => self.touchpad(id, data),
+            Role::Mouse => self.mouse(id, data),
+            Role::Keys => {}
+        }
+    }
+
+    fn fields(&self, id: u8) -> impl Iterator<Item = &Field> {
+        self.descriptor.fields.iter().filter(move |field| field.kind == ReportKind::Input && field.report_id == id)
+    }
+
+    // Consumer and system keys, variable or array
+    fn keys(&mut self, id: u8, data: &[u8]) {
+        let mut down = Vec::new();
+        for field in self.fields(id) {
+            for index in 0..field.count {
+                let value = field.value(data, index);
+                let usage = if field.variable() {
+                    if value == 0 {
+                        continue;
+                    }
+                    field.usage(index)
+                } else {
+                    let Ok(slot) = usize::try_from(value - field.logical_min) else {
+                        continue;
+                    };
+                    match field.usages.get(slot) {
+                        Some(&usage) => usage,
+                        None => continue,
+                    }
+                };
+                if usage >> 16 != PAGE_CONSUMER && usage != GD_SYSTEM_SLEEP && usage != GD_WIRELESS_RADIO_BUTTON {
+                    continue;
+                }
+                if let Some(&(_, code)) = KEYS.iter().find(|&&(known, _)| known == usage) {
+                    down.push(code);
+                }
+            }
+        }
+        let device = self.device;
+        let mut changed = false;
+        self.keys_down.retain(|&(report, code)| {
+            let released = report == id && !down.contains(&code);
+            if released {
+                super::key(device, code, false);
+                changed = true;
+            }
+            !released
+        });
+        for code in down {
+            if !self.keys_down.contains(&(id, code)) {
+                self.keys_down.push((id, code));
+                super::key(device, code, true);
+                changed = true;
+            }
+        }
+        if changed {
+            super::sync(device);
+        }
+    }
+
+    // A mouse, or a touchpad still in mouse mode
+    fn mouse(&mut self, id: u8, data: &[u8]) {
+        let (mut dx, mut dy, mut wheel, mut buttons, mut seen) = (0, 0, 0, 0u8, false);
+        for field in self.fields(id).filter(|field| field.variable()) {
+            for index in 0..field.count {
+                let value = field.value(data, index);
+                match field.usage(index) {
+                    GD_X if field.flags & FLAG_RELATIVE != 0 => dx = value,
+                    GD_Y if field.flags & FLAG_RELATIVE != 0 => dy = value,
+                    GD_WHEEL => wheel = value,
+                    usage if usage >> 16 == PAGE_BUTTON && (1..=3).contains(&(usage & 0xFFFF)) => {
+                        if value != 0 {
+                            buttons |= 1 << ((usage & 0xFFFF) - 1);
+                        }
+                    }
+                    _ => continue,
+                }
+                seen = true;
+            }
+        }
+        if !seen {
+            return;
+        }
+        self.pointer(dx, dy, wheel, buttons);
+    }
+
+    // Move the cursor and report the buttons that changed
+    fn pointer(&mut self, dx: i32, dy: i32, wheel: i32, buttons: u8) {
+        let changed = buttons ^ self.mouse_buttons;
+        for (bit, code) in [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE].into_iter().enumerate() {
+            if changed & (1 << bit) != 0 {
+                super::key(self.device, code, buttons & (1 << bit) != 0);
+            }
+        }
+        if dx != 0 {
+            super::report(self.device, super::EV_REL, super::REL_X, dx);
+        }
+        if dy != 0 {
+            super::report(self.device, super::EV_REL, super::REL_Y, dy);
+        }
+        if wheel != 0 {
+            super::report(self.device, super::EV_REL, super::REL_WHEEL, wheel);
+        }
+        super::sync(self.device);
+        self.mouse_buttons = buttons;
+        crate::drivers::mouse::inject(
+            dx.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            dy.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            buttons,
+            wheel.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+        );
+    }
+
+    // Gather a frame's contacts, which hybrid mode spreads over reports
+    fn touchpad(&mut self, id: u8, data: &[u8]) {
+        let mut contacts: Vec<Contact> = Vec::new();
+        let mut count = None;
+        let mut button = false;
+        for field in self.fields(id) {
+            for index in 0..field.count {
+                let usage = field.usage(index);
+                let value = field.value(data, index);
+                if let Some(finger) = field.finger {
+                    if contacts.len() <= finger {
+                        contacts.resize(finger + 1, Contact { confident: true, ..Contact::default() });
+                    }
+                    let contact = &mut contacts[finger];
+                    match usage {
+                        DIGITIZER_CONTACT_ID => contact.id = value,
+                        DIGITIZER_TIP_SWITCH => contact.tip = value != 0,
+                        DIGITIZER_CONFIDENCE => contact.confident = value != 0,
+                        GD_X => contact.x = value,
+                        GD_Y => contact.y = value,
+                        _ => {}
+                    }
+                } else if usage == DIGITIZER_CONTACT_COUNT {
+                    count = Some(value.max(0) as usize);
+                } else if usage >> 16 == PAGE_BUTTON && value != 0 {
+                    button = true;
+                }
+            }
+        }
+        // Fingers are numbered across the descriptor's reports; keep this
+        // report's
+        let first = self.fields(id).filter_map(|field| field.finger).min().unwrap_or(0);
+        let contacts = contacts.split_off(first.min(contacts.len()));
+
+        match count {
+            // Without a count, each report is a whole frame
+            None => {
+                self.touch.gathering.clear();
+                self.touch.expected = contacts.len();
+                self.touch.button = button;
+            }
+            Some(count) if count > 0 => {
+                self.touch.gathering.clear();
+                self.touch.expected = count;
+                self.touch.button = button;
+            }
+            // Hybrid mode continues the frame; a count of 0 with nothing
+            // gathered is every finger lifting
+            Some(_) if self.touch.expected > self.touch.gathering.len() => {}
+            Some(_) => {
+                self.touch.expected = 0;
+                self.touch.gathering.clear();
+                self.touch.button = button;
+            }
+        }
+        let room = self.touch.expected - self.touch.gathering.len();
+        self.touch.gathering.extend(contacts.into_iter().take(room));
+        if self.touch.gathering.len() >= self.touch.expected {
+            let frame = core::mem::take(&mut self.touch.gathering);
+            self.frame(&frame);
+        }
+    }
+
+    // A whole frame: multitouch events, then the pointer
+    fn frame(&mut self, contacts: &[Contact]) {
+        let Some(axes) = self.axes else {
+            return;
+        };
+        let device = self.device;
+        let touching: Vec<Contact> = contacts.iter().filter(|c| c.tip && c.confident).copied().collect();
+        let previous = self.touch.slots.clone();
+
+        // Lift the contacts that are gone, then place the rest, each in
+        // the slot it had or the first free one
+        for (slot, held) in self.touch.slots.iter_mut().enumerate() {
+            if held.is_some_and(|held| !touching.iter().any(|c| c.id == held.id)) {
+                *held = None;
+                super::report(device, EV_ABS, ABS_MT_SLOT, slot as i32);
+                super::report(device, EV_ABS, ABS_MT_TRACKING_ID, -1);
+            }
+        }
+        for contact in &touching {
+            let slot = match self.touch.slots.iter().position(|held| held.is_some_and(|h| h.id == contact.id)) {
+                Some(slot) => slot,
+                None => {
+                    let slot = match self.touch.slots.iter().position(Option::is_none) {
+                        Some(slot) => slot,
+                        None => {
+                            self.touch.slots.push(None);
+                            self.touch.tracking_ids.push(0);
+                            self.touch.slots.len() - 1
+                        }
+                    };
+                    self.touch.tracking_ids[slot] = self.touch.next_tracking_id;
+                    self.touch.next_tracking_id = self.touch.next_tracking_id.wrapping_add(1) & 0xFFFF;
+                    super::report(device, EV_ABS, ABS_MT_SLOT, slot as i32);
+                    super::report(device, EV_ABS, ABS_MT_TRACKING_ID, self.touch.tracking_ids[slot]);
+                    slot
+                }
+            };
+            self.touch.slots[slot] = Some(*contact);
+            super::report(device, EV_ABS, ABS_MT_SLOT, slot as i32);
+            super::report(device, EV_ABS, ABS_MT_POSITION_X, contact.x);
+            super::report(device, EV_ABS, ABS_MT_POSITION_Y, contact.y);
+        }
+        if let Some(first) = touching.first() {
+            super::report(device, EV_ABS, ABS_X, first.x);
+            super::report(device, EV_ABS, ABS_Y, first.y);
+        }
+        let was_touching = previous.iter().any(Option::is_some);
+        if was_touching != !touching.is_empty() {
+            super::key(device, BTN_TOUCH, !touching.is_empty());
+        }
+        let fingers = |n: usize| touching.len() == n;
+        let had = |n: usize| previous.iter().flatten().count() == n;
+        if fingers(1) != had(1) {
+            super::key(device, BTN_TOOL_FINGER, fingers(1));
+        }
+        if fingers(2) != had(2) {
+            super::key(device, BTN_TOOL_DOUBLETAP, fingers(2));
+        }
+
+        // Movement of the contacts that were down last frame too
+        let moved: Vec<(i32, i32)> = touching
+            .iter()
+            .filter_map(|c| previous.iter().flatten().find(|p| p.id == c.id).map(|p| (c.x - p.x, c.y - p.y)))
+            .collect();
+        let (mut dx, mut dy, mut wheel) = (0, 0, 0);
+        match (touching.len(), moved.as_slice()) {
+            (1, [(mx, my)]) => {
+                dx = mx * PAD_WIDTH_PIXELS / (axes.x_max - axes.x_min);
+                dy = my * PAD_WIDTH_PIXELS / (axes.x_max - axes.x_min);
+            }
+            (2, [(_, a), (_, b)]) => {
+                // Natural direction: fingers moving up scroll down
+                self.touch.scroll += (a + b) / 2;
+                let step = ((axes.y_max - axes.y_min) / SCROLL_STEP_DIVISOR).max(1);
+                wheel = self.touch.scroll / step;
+                self.touch.scroll -= wheel * step;
+            }
+            _ => self.touch.scroll = 0,
+        }
+        let buttons = self.touch.button as u8;
+        if dx != 0 || dy != 0 || wheel != 0 || buttons != self.mouse_buttons || !had(touching.len()) {
+            self.pointer(dx, dy, wheel, buttons);
+        } else {
+            super::sync(device);
+        }
+    }
+}
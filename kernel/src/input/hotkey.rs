@@ -0,0 +1,70 @@
+//! Keys the kernel acts on itself
+//!
+//! Brightness keys step the panel backlight, volume keys the master volume
+//! and mute, and radio keys flip rfkill's soft blocks. Whatever device the
+//! key came from, it still reaches `read` as an event.
+
+use spin::Mutex;
+
+use super::{
+    KEY_BLUETOOTH, KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP, KEY_MUTE, KEY_RFKILL, KEY_VOLUMEDOWN, KEY_VOLUMEUP, KEY_WLAN,
+};
+use crate::power::rfkill::{self, Radio};
+use crate::sound::AUDIO_MANAGER;
+
+const BRIGHTNESS_STEP: i8 = 10;
+const VOLUME_STEP: f32 = 0.1;
+
+// The volume to go back to when mute is pressed again
+static MUTED: Mutex<Option<f32>> = Mutex::new(None);
+
+fn change_volume(delta: f32) {
+    let mut manager = AUDIO_MANAGER.lock();
+    let volume = MUTED.lock().take().unwrap_or(manager.get_volume());
+    let _ = manager.set_volume(volume + delta);
+}
+
+fn toggle_mute() {
+    let mut manager = AUDIO_MANAGER.lock();
+    let mut muted = MUTED.lock();
+    let volume = match muted.take() {
+        Some(volume) => volume,
+        None => {
+            *muted = Some(manager.get_volume());
+            0.0
+        }
+    };
+    let _ = manager.set_volume(volume);
+}
+
+/// Whether the volume is muted
+pub fn muted() -> bool {
+    MUTED.lock().is_some()
+}
+
+/// A key has gone down
+pub fn key_pressed(code: u16) {
+    let brightness = match code {
+        KEY_BRIGHTNESSUP => crate::gpu::backlight::step(BRIGHTNESS_STEP),
+        KEY_BRIGHTNESSDOWN => crate::gpu::backlight::step(-BRIGHTNESS_STEP),
+        KEY_VOLUMEUP => return change_volume(VOLUME_STEP),
+        KEY_VOLUMEDOWN => return change_volume(-VOLUME_STEP),
+        KEY_MUTE => return toggle_mute(),
+        KEY_WLAN => {
+            rfkill::toggle(Radio::Wlan);
+            return;
+        }
+        KEY_BLUETOOTH => {
+            rfkill::toggle(Radio::Bluetooth);
+            return;
+        }
+        KEY_RFKILL => {
+            rfkill::toggle_all();
+            return;
+        }
+        _ => return,
+    };
+    if let Err(e) = brightness {
+        crate::serial_println!("hotkey: brightness: {}", e);
+    }
+}
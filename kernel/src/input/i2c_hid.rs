@@ -0,0 +1,203 @@
+//! HID over I2C: laptop touchpads
+//!
+//! Firmware gives each device's address and the register holding its HID
+//! descriptor in the ACPI namespace, which is not read. Each bus is tried
+//! instead at the addresses and registers the common touchpads use, and an
+//! answer that is a valid descriptor, 30 bytes of version 1.00, is taken as
+//! a device. It is powered on and reset, its report descriptor read, and a
+//! touchpad is switched out of mouse mode.
+//!
+//! The interrupt line is a GPIO the namespace names too, so devices are
+//! read every few milliseconds rather than when they signal. One with
+//! nothing new answers with a length of 0 or its last report again, and
+//! neither moves anything.
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::hid::{HidInput, ReportDescriptor, Role};
+use super::DeviceKind;
+use crate::time::clocksource::now_ns;
+
+// Addresses and descriptor registers: Synaptics, ELAN, ALPS and FocalTech
+const CANDIDATES: [(u8, u16); 4] = [(0x2C, 0x0020), (0x15, 0x0001), (0x2C, 0x0001), (0x38, 0x0001)];
+
+const DESCRIPTOR_LENGTH: usize = 30;
+const HID_VERSION: u16 = 0x0100;
+
+const OPCODE_RESET: u8 = 0x1;
+const OPCODE_SET_REPORT: u8 = 0x3;
+const OPCODE_SET_POWER: u8 = 0x8;
+const POWER_ON: u8 = 0;
+const REPORT_TYPE_FEATURE: u8 = 3;
+// Report IDs from 15 up follow the command in a byte of their own
+const REPORT_ID_EXTENDED: u8 = 0xF;
+
+// Largest report descriptor and input report taken
+const MAX_REPORT_DESCRIPTOR: usize = 4096;
+const MAX_INPUT_LENGTH: usize = 512;
+
+const RESET_TIMEOUT_MS: u64 = 1000;
+// Devices are read at 125 Hz
+const POLL_INTERVAL_NS: u64 = 8_000_000;
+
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+struct HidDescriptor {
+    report_descriptor_length: usize,
+    report_descriptor_register: u16,
+    max_input_length: usize,
+    command_register: u16,
+    data_register: u16,
+    vendor: u16,
+    product: u16,
+}
+
+impl HidDescriptor {
+    fn parse(data: &[u8; DESCRIPTOR_LENGTH]) -> Option<HidDescriptor> {
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        if word(0) as usize != DESCRIPTOR_LENGTH || word(2) != HID_VERSION {
+            return None;
+        }
+        Some(HidDescriptor {
+            report_descriptor_length: word(4) as usize,
+            report_descriptor_register: word(6),
+            max_input_length: (word(10) as usize).clamp(2, MAX_INPUT_LENGTH),
+            command_register: word(16),
+            data_register: word(18),
+            vendor: word(20),
+            product: word(22),
+        })
+    }
+}
+
+struct I2cHid {
+    bus: usize,
+    address: u8,
+    descriptor: HidDescriptor,
+    input: HidInput,
+}
+
+static DEVICES: Mutex<Vec<I2cHid>> = Mutex::new(Vec::new());
+
+fn read_register(bus: usize, address: u8, register: u16, buffer: &mut [u8]) -> Result<(), &'static str> {
+    crate::i2c::write_read(bus, address, &register.to_le_bytes(), buffer)
+}
+
+// A command to the command register: its opcode and low byte, then any
+// bytes that follow it
+fn command(bus: usize, address: u8, hid: &HidDescriptor, opcode: u8, low: u8, rest: &[u8]) -> Result<(), &'static str> {
+    let mut bytes = Vec::with_capacity(4 + rest.len());
+    bytes.extend_from_slice(&hid.command_register.to_le_bytes());
+    bytes.push(low);
+    bytes.push(opcode);
+    bytes.extend_from_slice(rest);
+    crate::i2c::write(bus, address, &bytes)
+}
+
+fn set_feature(bus: usize, address: u8, hid: &HidDescriptor, id: u8, data: &[u8]) -> Result<(), &'static str> {
+    let mut rest = Vec::new();
+    if id >= REPORT_ID_EXTENDED {
+        rest.push(id);
+    }
+    rest.extend_from_slice(&hid.data_register.to_le_bytes());
+    let length = 2 + (id != 0) as usize + data.len();
+    rest.extend_from_slice(&(length as u16).to_le_bytes());
+    if id != 0 {
+        rest.push(id);
+    }
+    rest.extend_from_slice(data);
+    let low = REPORT_TYPE_FEATURE << 4 | id.min(REPORT_ID_EXTENDED);
+    command(bus, address, hid, OPCODE_SET_REPORT, low, &rest)
+}
+
+// Reset, then wait for the empty report that says it is done
+fn reset(bus: usize, address: u8, hid: &HidDescriptor) -> Result<(), &'static str> {
+    command(bus, address, hid, OPCODE_SET_POWER, POWER_ON, &[])?;
+    command(bus, address, hid, OPCODE_RESET, 0, &[])?;
+    let deadline = now_ns() + RESET_TIMEOUT_MS * 1_000_000;
+    let mut buffer = vec![0u8; hid.max_input_length];
+    while now_ns() < deadline {
+        if crate::i2c::read(bus, address, &mut buffer).is_ok() && buffer[..2] == [0, 0] {
+            return Ok(());
+        }
+    }
+    Err("no reset response")
+}
+
+// Bring up the device at `address`, if one answers there
+fn attach(bus: usize, address: u8, register: u16) -> Result<I2cHid, &'static str> {
+    let mut raw = [0u8; DESCRIPTOR_LENGTH];
+    read_register(bus, address, register, &mut raw)?;
+    let hid = HidDescriptor::parse(&raw).ok_or("not a HID descriptor")?;
+    if hid.report_descriptor_length == 0 || hid.report_descriptor_length > MAX_REPORT_DESCRIPTOR {
+        return Err("bad report descriptor length");
+    }
+    reset(bus, address, &hid)?;
+    let mut raw = vec![0u8; hid.report_descriptor_length];
+    read_register(bus, address, hid.report_descriptor_register, &mut raw)?;
+    let descriptor = ReportDescriptor::parse(&raw)?;
+
+    let name = format!("i2c-hid {:04x}:{:04x}", hid.vendor, hid.product);
+    let mut input = HidInput::new(descriptor, 0);
+    let kind = match input.role {
+        Role::Touchpad => DeviceKind::Touchpad,
+        Role::Mouse => DeviceKind::Mouse,
+        Role::Keys => DeviceKind::Keys,
+    };
+    if let Some((id, data)) = input.input_mode_report() {
+        set_feature(bus, address, &hid, id, &data)?;
+    }
+    input.device = super::register(&name, kind);
+    crate::serial_println!("i2c-hid: {} at i2c-{} 0x{:02x}, {}", name, bus, address, kind.name());
+    Ok(I2cHid { bus, address, descriptor: hid, input })
+}
+
+/// Look for devices on a bus that has just registered
+pub fn scan(bus: usize) {
+    let mut found: Vec<u8> = Vec::new();
+    for (address, register) in CANDIDATES {
+        if found.contains(&address) {
+            continue;
+        }
+        if let Ok(device) = attach(bus, address, register) {
+            found.push(address);
+            DEVICES.lock().push(device);
+        }
+    }
+}
+
+/// Let go of the devices on a bus that has gone
+pub fn detach_bus(bus: usize) {
+    DEVICES.lock().retain(|device| {
+        if device.bus == bus {
+            super::unregister(device.input.device);
+        }
+        device.bus != bus
+    });
+}
+
+/// Read each device's input report
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    let mut devices = DEVICES.lock();
+    for device in devices.iter_mut() {
+        let mut buffer = vec![0u8; device.descriptor.max_input_length];
+        if crate::i2c::read(device.bus, device.address, &mut buffer).is_err() {
+            continue;
+        }
+        let length = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+        if length > 2 && length <= buffer.len() {
+            device.input.report(&buffer[2..length]);
+        }
+    }
+}
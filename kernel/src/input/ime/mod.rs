@@ -0,0 +1,233 @@
+//! Input methods, for text a keyboard has no keys for
+//!
+//! With an input method on, what the keyboard types goes to it before it
+//! is typed: it builds up the preedit text, offers candidates for it, and
+//! commits the one chosen. Keys it does not take are typed as usual.
+//! Ctrl+Space turns the method on and off, and `input ime` chooses it.
+//!
+//! Two kinds are here: `unicode`, which takes a character's hex code, and
+//! tables loaded from files, each mapping what is typed to candidates,
+//! as for pinyin or kana; see `table`.
+//!
+//! While something is being composed the candidate UI shows it. The
+//! console's draws the preedit and candidates on the top line of the text
+//! screen; a GUI puts in its own with `set_candidate_ui`.
+
+pub mod table;
+pub mod unicode;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+/// Candidates shown at a time, each picked with its digit
+pub const PAGE_SIZE: usize = 9;
+
+/// A key as an input method sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Space,
+    Backspace,
+    Enter,
+    Escape,
+    Up,
+    Down,
+}
+
+/// What is being composed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Composition {
+    pub preedit: String,
+    pub candidates: Vec<String>,
+    /// The candidate Space would commit
+    pub selected: usize,
+}
+
+impl Composition {
+    const fn new() -> Self {
+        Composition { preedit: String::new(), candidates: Vec::new(), selected: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.preedit.is_empty()
+    }
+
+    /// The page of candidates the selected one is on, and where it starts
+    pub fn page(&self) -> (usize, &[String]) {
+        let start = self.selected / PAGE_SIZE * PAGE_SIZE;
+        let end = (start + PAGE_SIZE).min(self.candidates.len());
+        (start, self.candidates.get(start..end).unwrap_or(&[]))
+    }
+}
+
+pub trait InputMethod: Send {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// Take a key, adding any text settled on to `commit`; false leaves
+    /// the key to be typed as usual
+    fn key(&mut self, key: Key, commit: &mut String) -> bool;
+
+    fn composition(&self) -> Composition;
+
+    /// Drop what is being composed
+    fn reset(&mut self);
+}
+
+/// Shows what is being composed; called with an empty composition when
+/// there is nothing left to show
+pub type CandidateUi = fn(&Composition);
+
+struct Ime {
+    methods: Vec<Box<dyn InputMethod>>,
+    selected: usize,
+    enabled: bool,
+    ui: Option<CandidateUi>,
+    // The composition last shown, to tell when it changes
+    shown: Composition,
+}
+
+static IME: Mutex<Ime> =
+    Mutex::new(Ime { methods: Vec::new(), selected: 0, enabled: false, ui: None, shown: Composition::new() });
+
+fn with<T>(f: impl FnOnce(&mut Ime) -> T) -> T {
+    let mut ime = IME.lock();
+    if ime.methods.is_empty() {
+        ime.methods.push(Box::new(unicode::Unicode::new()));
+    }
+    f(&mut ime)
+}
+
+/// Add a method, replacing one of the same name
+pub fn register(method: Box<dyn InputMethod>) {
+    with(|ime| match ime.methods.iter().position(|known| known.name() == method.name()) {
+        Some(index) => ime.methods[index] = method,
+        None => ime.methods.push(method),
+    });
+}
+
+/// The methods, as (name, description), and which is chosen and whether
+/// it is on
+pub fn methods() -> (Vec<(String, String)>, usize, bool) {
+    with(|ime| {
+        let names = ime.methods.iter().map(|method| (String::from(method.name()), String::from(method.description())));
+        (names.collect(), ime.selected, ime.enabled)
+    })
+}
+
+/// Choose a method by name and turn it on
+pub fn select(name: &str) -> Result<(), &'static str> {
+    with(|ime| {
+        let index = ime.methods.iter().position(|method| method.name() == name).ok_or("no such input method")?;
+        let selected = ime.selected;
+        ime.methods[selected].reset();
+        ime.selected = index;
+        ime.enabled = true;
+        Ok(())
+    })
+}
+
+pub fn set_enabled(enabled: bool) {
+    with(|ime| {
+        let selected = ime.selected;
+        ime.methods[selected].reset();
+        ime.enabled = enabled;
+    });
+}
+
+/// Turn the method on or off, as Ctrl+Space does
+pub fn toggle() {
+    let enabled = with(|ime| ime.enabled);
+    set_enabled(!enabled);
+}
+
+/// Give a key to the method, if it is on; false if it leaves the key
+pub fn key(key: Key, commit: &mut String) -> bool {
+    with(|ime| {
+        let selected = ime.selected;
+        ime.enabled && ime.methods[selected].key(key, commit)
+    })
+}
+
+/// What is being composed now
+pub fn composition() -> Composition {
+    with(|ime| {
+        let selected = ime.selected;
+        if ime.enabled {
+            ime.methods[selected].composition()
+        } else {
+            Composition::default()
+        }
+    })
+}
+
+/// Show the candidates with `ui`, or with the console's when None
+pub fn set_candidate_ui(ui: Option<CandidateUi>) {
+    let previous = with(|ime| {
+        ime.shown = Composition::default();
+        core::mem::replace(&mut ime.ui, ui)
+    });
+    // Let the old UI take down what it showed
+    previous.unwrap_or(console_ui)(&Composition::default());
+}
+
+/// Show the composition if it changed since it was last shown; called
+/// from `input::poll`, with no input lock held
+pub fn update_ui() {
+    let (ui, composition) = {
+        let current = composition();
+        let mut ime = IME.lock();
+        if current == ime.shown {
+            return;
+        }
+        ime.shown = current.clone();
+        (ime.ui.unwrap_or(console_ui), current)
+    };
+    ui(&composition);
+}
+
+// The top line of the text screen, as it was before the candidates
+// covered it
+static SAVED_LINE: Mutex<Option<Vec<(u8, u8)>>> = Mutex::new(None);
+
+// White on blue for the line, black on light grey for the selected
+// candidate
+const LINE_ATTRIBUTE: u8 = 0x1F;
+const SELECTED_ATTRIBUTE: u8 = 0x70;
+
+// The console's candidate UI: the preedit in brackets, then the page of
+// candidates, numbered, on the top line
+fn console_ui(composition: &Composition) {
+    use crate::vga_buffer::{blit_cells, read_cells, text_byte, TEXT_WIDTH};
+
+    let mut saved = SAVED_LINE.lock();
+    if composition.is_empty() {
+        if let Some(line) = saved.take() {
+            blit_cells(&line, TEXT_WIDTH);
+        }
+        return;
+    }
+    if saved.is_none() {
+        *saved = Some(read_cells().into_iter().take(TEXT_WIDTH).collect());
+    }
+
+    let mut line: Vec<(u8, u8)> = Vec::with_capacity(TEXT_WIDTH);
+    let mut put = |text: &str, attribute: u8| {
+        line.extend(text.chars().map(|c| (text_byte(c), attribute)));
+    };
+    put("[", LINE_ATTRIBUTE);
+    put(&composition.preedit, LINE_ATTRIBUTE);
+    put("]", LINE_ATTRIBUTE);
+    let (start, page) = composition.page();
+    for (index, candidate) in page.iter().enumerate() {
+        let attribute = if start + index == composition.selected { SELECTED_ATTRIBUTE } else { LINE_ATTRIBUTE };
+        put(&alloc::format!(" {}.", index + 1), LINE_ATTRIBUTE);
+        put(candidate, attribute);
+    }
+    line.resize(TEXT_WIDTH, (b' ', LINE_ATTRIBUTE));
+    blit_cells(&line, TEXT_WIDTH);
+}
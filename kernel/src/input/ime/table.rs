@@ -0,0 +1,179 @@
+//! Input methods from tables
+//!
+//! A table maps what is typed, such as a pinyin syllable or romaji, to
+//! the text it may stand for. It is loaded from a file with
+//! `input ime load`:
+//!
+//! ```text
+//! name pinyin
+//! description Pinyin, a few syllables
+//! ni = 你 尼 泥
+//! hao = 好 号
+//! nihao = 你好
+//! ```
+//!
+//! Letters typed build up the preedit. Its candidates are the entries it
+//! names, then those it begins; 1 to 9 pick from the page shown, Up and
+//! Down move through them, Space commits the one selected and Enter the
+//! preedit as typed. Any other key commits the selected candidate and is
+//! then typed itself.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{Composition, InputMethod, Key, PAGE_SIZE};
+
+// Entries a table may hold
+const MAX_ENTRIES: usize = 65536;
+
+pub struct Table {
+    name: String,
+    description: String,
+    entries: BTreeMap<String, Vec<String>>,
+    preedit: String,
+    selected: usize,
+}
+
+impl Table {
+    /// Read a table file
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (mut name, mut description) = (None, None);
+        let mut entries: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let fail = |reason: &str| format!("line {}: {}", number + 1, reason);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match word {
+                "name" => name = Some(rest.trim()),
+                "description" => description = Some(rest.trim()),
+                _ => {
+                    let (key, candidates) =
+                        line.split_once('=').ok_or_else(|| fail("expected name, description or key = ..."))?;
+                    let key = key.trim();
+                    if key.is_empty() || !key.chars().all(|c| c.is_ascii_graphic() && !c.is_ascii_digit()) {
+                        return Err(fail("a key is letters and punctuation, without digits"));
+                    }
+                    if entries.len() >= MAX_ENTRIES {
+                        return Err(fail("too many entries"));
+                    }
+                    let list = entries.entry(String::from(key)).or_default();
+                    list.extend(candidates.split_whitespace().map(String::from));
+                }
+            }
+        }
+
+        let name = name.filter(|name| !name.is_empty() && !name.contains(char::is_whitespace));
+        let name = name.ok_or("the table needs a name, one word")?;
+        Ok(Table {
+            name: String::from(name),
+            description: String::from(description.unwrap_or(name)),
+            entries,
+            preedit: String::new(),
+            selected: 0,
+        })
+    }
+
+    // The candidates for the preedit: what it names, then what it begins
+    fn candidates(&self) -> Vec<String> {
+        if self.preedit.is_empty() {
+            return Vec::new();
+        }
+        let matching = self.entries.range(self.preedit.clone()..).take_while(|(key, _)| key.starts_with(&self.preedit));
+        matching.flat_map(|(_, candidates)| candidates.iter().cloned()).collect()
+    }
+
+    // Whether some entry begins with the preedit and `c`
+    fn continues(&self, c: char) -> bool {
+        let mut prefix = self.preedit.clone();
+        prefix.push(c);
+        self.entries.range(prefix.clone()..).next().is_some_and(|(key, _)| key.starts_with(&prefix))
+    }
+
+    // Commit the selected candidate, or the preedit if there are none
+    fn commit_selected(&mut self, commit: &mut String) {
+        let candidates = self.candidates();
+        match candidates.get(self.selected) {
+            Some(candidate) => commit.push_str(candidate),
+            None => commit.push_str(&self.preedit),
+        }
+        self.reset();
+    }
+}
+
+/// Load a table file and register it
+pub fn load(path: &str) -> Result<String, String> {
+    let data = crate::fs::vfs::VFS.lock().read_file(path).map_err(|error| format!("{}: {:?}", path, error))?;
+    let text = core::str::from_utf8(&data).map_err(|_| format!("{}: not UTF-8", path))?;
+    let table = Table::parse(text).map_err(|reason| format!("{}: {}", path, reason))?;
+    if table.name == "unicode" {
+        return Err(format!("{}: 'unicode' is built in", path));
+    }
+    let name = table.name.clone();
+    super::register(alloc::boxed::Box::new(table));
+    Ok(name)
+}
+
+impl InputMethod for Table {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn key(&mut self, key: Key, commit: &mut String) -> bool {
+        let composing = !self.preedit.is_empty();
+        match key {
+            Key::Char(c) if self.continues(c) => {
+                self.preedit.push(c);
+                self.selected = 0;
+            }
+            Key::Char(digit @ '1'..='9') if composing => {
+                let candidates = self.candidates();
+                let start = self.selected / PAGE_SIZE * PAGE_SIZE;
+                let index = start + digit as usize - '1' as usize;
+                if let Some(candidate) = candidates.get(index) {
+                    commit.push_str(candidate);
+                    self.reset();
+                }
+            }
+            Key::Space if composing => self.commit_selected(commit),
+            Key::Enter if composing => {
+                commit.push_str(&self.preedit);
+                self.reset();
+            }
+            Key::Up if composing => self.selected = self.selected.saturating_sub(1),
+            Key::Down if composing => {
+                self.selected = (self.selected + 1).min(self.candidates().len().saturating_sub(1))
+            }
+            Key::Backspace if composing => {
+                self.preedit.pop();
+                self.selected = 0;
+            }
+            Key::Escape if composing => self.reset(),
+            _ => {
+                if composing {
+                    self.commit_selected(commit);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    fn composition(&self) -> Composition {
+        Composition { preedit: self.preedit.clone(), candidates: self.candidates(), selected: self.selected }
+    }
+
+    fn reset(&mut self) {
+        self.preedit.clear();
+        self.selected = 0;
+    }
+}
@@ -0,0 +1,83 @@
+//! Typing a character by its code
+//!
+//! `u` starts the code, the hex digits follow, and Space or Enter types
+//! the character, as GTK's Ctrl+Shift+U does. Escape drops it. Keys typed
+//! while nothing is started pass through.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{Composition, InputMethod, Key};
+
+// Hex digits in the largest code point
+const MAX_DIGITS: usize = 6;
+
+pub struct Unicode {
+    started: bool,
+    digits: String,
+}
+
+impl Unicode {
+    pub const fn new() -> Self {
+        Unicode { started: false, digits: String::new() }
+    }
+
+    fn character(&self) -> Option<char> {
+        char::from_u32(u32::from_str_radix(&self.digits, 16).ok()?)
+    }
+}
+
+impl Default for Unicode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputMethod for Unicode {
+    fn name(&self) -> &str {
+        "unicode"
+    }
+
+    fn description(&self) -> &str {
+        "Characters by hex code, after u"
+    }
+
+    fn key(&mut self, key: Key, commit: &mut String) -> bool {
+        if !self.started {
+            self.started = matches!(key, Key::Char('u' | 'U'));
+            return self.started;
+        }
+        match key {
+            Key::Char(c) if c.is_ascii_hexdigit() && self.digits.len() < MAX_DIGITS => self.digits.push(c),
+            Key::Backspace => {
+                if self.digits.pop().is_none() {
+                    self.started = false;
+                }
+            }
+            Key::Space | Key::Enter => {
+                commit.extend(self.character());
+                self.reset();
+            }
+            Key::Escape => self.reset(),
+            _ => {}
+        }
+        true
+    }
+
+    fn composition(&self) -> Composition {
+        if !self.started {
+            return Composition::default();
+        }
+        let candidates: Vec<String> = match self.character() {
+            Some(c) => vec![String::from(c)],
+            None => Vec::new(),
+        };
+        Composition { preedit: alloc::format!("u{}", self.digits), candidates, selected: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.started = false;
+        self.digits.clear();
+    }
+}
@@ -0,0 +1,453 @@
+//! Keyboard layouts: what the console types for each key
+//!
+//! Keys arrive as Linux key codes, which name a key by where it sits on a
+//! US keyboard. A layout gives the symbol each key in the main block
+//! types alone, with Shift and with AltGr; Caps Lock shifts the letters
+//! only. Keys that are the same everywhere, Enter and the cursor keys
+//! among them, send what a VT100 terminal would.
+//!
+//! A symbol is a character or a dead key. A dead key types nothing itself
+//! but puts its accent on the next letter, as `compose` says; followed by
+//! Space, or by a letter the accent does not go on, it types the accent.
+//!
+//! Besides the layouts built in, more are loaded from files, kept in
+//! `/etc/keymaps` as `<name>.map`, or loaded from anywhere with
+//! `input keymap load`:
+//!
+//! ```text
+//! name sv
+//! description Swedish
+//! keycode 12 = + ? \
+//! keycode 13 = dead_acute dead_grave
+//! keycode 26 = å Å
+//! ```
+//!
+//! Each `keycode` line gives a key's symbols alone, with Shift and with
+//! AltGr: a character, `U+` and its hex code, a `dead_` accent, or `-` for
+//! nothing. Keys a file leaves out type as on a US keyboard.
+//!
+//! The layout is chosen at boot with `keymap=`, and later with
+//! `input keymap`.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{
+    KEY_BACKSPACE, KEY_DELETE, KEY_DOWN, KEY_END, KEY_ENTER, KEY_ESC, KEY_HOME, KEY_INSERT, KEY_KPASTERISK,
+    KEY_KPENTER, KEY_KPMINUS, KEY_KPPLUS, KEY_KPSLASH, KEY_LEFT, KEY_PAGEDOWN, KEY_PAGEUP, KEY_RIGHT, KEY_SPACE,
+    KEY_TAB, KEY_UP,
+};
+use crate::boot::cmdline::Param;
+
+pub const KEYMAP_DIR: &str = "/etc/keymaps";
+
+// Layouts loaded from files, at most
+const MAX_LOADED: usize = 16;
+// The highest key code a file may set
+const MAX_KEYCODE: u16 = 255;
+
+/// The modifiers held, or locked, as a key goes down
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub altgr: bool,
+    pub caps_lock: bool,
+}
+
+/// What a key types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keysym {
+    /// What a terminal sends for a key that is not a character
+    Terminal(&'static str),
+    Char(char),
+    /// A dead key, with its accent
+    Dead(char),
+}
+
+pub struct Layout {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Runs of neighbouring keys, by the code of the first: what each types
+    // alone and with Shift
+    rows: &'static [(&'static str, &'static str, u16)],
+    // What AltGr types, by key
+    altgr: &'static [(u16, char)],
+    // Keys whose accents are dead keys
+    dead: &'static [u16],
+    // A loaded layout's keys, by code: alone, with Shift and with AltGr
+    keys: &'static [(u16, [Option<Keysym>; 3])],
+}
+
+// Keys that type the same on every layout
+const TERMINAL: [(u16, &str); 20] = [
+    (KEY_ESC, "\x1b"),
+    (KEY_BACKSPACE, "\x08"),
+    (KEY_TAB, "\t"),
+    (KEY_ENTER, "\n"),
+    (KEY_SPACE, " "),
+    (KEY_KPASTERISK, "*"),
+    (KEY_KPMINUS, "-"),
+    (KEY_KPPLUS, "+"),
+    (KEY_KPENTER, "\n"),
+    (KEY_KPSLASH, "/"),
+    (KEY_HOME, "\x1b[H"),
+    (KEY_UP, "\x1b[A"),
+    (KEY_PAGEUP, "\x1b[5~"),
+    (KEY_LEFT, "\x1b[D"),
+    (KEY_RIGHT, "\x1b[C"),
+    (KEY_END, "\x1b[F"),
+    (KEY_DOWN, "\x1b[B"),
+    (KEY_PAGEDOWN, "\x1b[6~"),
+    (KEY_INSERT, "\x1b[2~"),
+    (KEY_DELETE, "\x1b[3~"),
+];
+
+// Accents, each as the characters dead keys give for it, the letters it
+// goes on, and what it makes of them
+const COMPOSE: [(&str, &str, &str); 6] = [
+    ("´'", "aeiouyAEIOUYcCnNsSzZ", "áéíóúýÁÉÍÓÚÝćĆńŃśŚźŹ"),
+    ("`", "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ("^", "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ("¨\"", "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+    ("~", "anoANO", "ãñõÃÑÕ"),
+    ("¸", "cC", "çÇ"),
+];
+
+// Dead keys' names in layout files
+const DEAD_NAMES: [(&str, char); 6] = [
+    ("dead_acute", '´'),
+    ("dead_grave", '`'),
+    ("dead_circumflex", '^'),
+    ("dead_diaeresis", '¨'),
+    ("dead_tilde", '~'),
+    ("dead_cedilla", '¸'),
+];
+
+const US_ROWS: [(&str, &str, u16); 5] = [
+    ("1234567890-=", "!@#$%^&*()_+", 2),
+    ("qwertyuiop[]", "QWERTYUIOP{}", 16),
+    ("asdfghjkl;'`", "ASDFGHJKL:\"~", 30),
+    ("\\zxcvbnm,./", "|ZXCVBNM<>?", 43),
+    ("\\", "|", 86),
+];
+
+pub static US: Layout =
+    Layout { name: "us", description: "US QWERTY", rows: &US_ROWS, altgr: &[], dead: &[], keys: &[] };
+
+pub static US_INTL: Layout = Layout {
+    name: "us-intl",
+    description: "US international, with dead keys",
+    rows: &US_ROWS,
+    altgr: &[
+        (3, '¡'),
+        (12, '¥'),
+        (17, 'å'),
+        (18, 'é'),
+        (24, 'ó'),
+        (30, 'á'),
+        (31, 'ß'),
+        (46, '©'),
+        (49, 'ñ'),
+        (53, '¿'),
+    ],
+    dead: &[7, 40, 41],
+    keys: &[],
+};
+
+pub static UK: Layout = Layout {
+    name: "uk",
+    description: "UK QWERTY",
+    rows: &[
+        ("1234567890-=", "!\"£$%^&*()_+", 2),
+        ("qwertyuiop[]", "QWERTYUIOP{}", 16),
+        ("asdfghjkl;'`", "ASDFGHJKL:@¬", 30),
+        ("#zxcvbnm,./", "~ZXCVBNM<>?", 43),
+        ("\\", "|", 86),
+    ],
+    altgr: &[(5, '€')],
+    dead: &[],
+    keys: &[],
+};
+
+pub static DE: Layout = Layout {
+    name: "de",
+    description: "German QWERTZ",
+    rows: &[
+        ("1234567890ß´", "!\"§$%&/()=?`", 2),
+        ("qwertzuiopü+", "QWERTZUIOPÜ*", 16),
+        ("asdfghjklöä^", "ASDFGHJKLÖÄ°", 30),
+        ("#yxcvbnm,.-", "'YXCVBNM;:_", 43),
+        ("<", ">", 86),
+    ],
+    altgr: &[
+        (3, '²'),
+        (4, '³'),
+        (8, '{'),
+        (9, '['),
+        (10, ']'),
+        (11, '}'),
+        (12, '\\'),
+        (16, '@'),
+        (18, '€'),
+        (27, '~'),
+        (50, 'µ'),
+        (86, '|'),
+    ],
+    dead: &[13, 41],
+    keys: &[],
+};
+
+pub static FR: Layout = Layout {
+    name: "fr",
+    description: "French AZERTY",
+    rows: &[
+        ("&é\"'(-è_çà)=", "1234567890°+", 2),
+        ("azertyuiop^$", "AZERTYUIOP¨£", 16),
+        ("qsdfghjklmù²", "QSDFGHJKLM%²", 30),
+        ("*wxcvbn,;:!", "µWXCVBN?./§", 43),
+        ("<", ">", 86),
+    ],
+    altgr: &[
+        (3, '~'),
+        (4, '#'),
+        (5, '{'),
+        (6, '['),
+        (7, '|'),
+        (8, '`'),
+        (9, '\\'),
+        (10, '^'),
+        (11, '@'),
+        (12, ']'),
+        (13, '}'),
+        (18, '€'),
+        (27, '¤'),
+    ],
+    dead: &[26],
+    keys: &[],
+};
+
+pub static ES: Layout = Layout {
+    name: "es",
+    description: "Spanish QWERTY",
+    rows: &[
+        ("1234567890'¡", "!\"·$%&/()=?¿", 2),
+        ("qwertyuiop`+", "QWERTYUIOP^*", 16),
+        ("asdfghjklñ´º", "ASDFGHJKLÑ¨ª", 30),
+        ("çzxcvbnm,.-", "ÇZXCVBNM;:_", 43),
+        ("<", ">", 86),
+    ],
+    altgr: &[
+        (2, '|'),
+        (3, '@'),
+        (4, '#'),
+        (5, '~'),
+        (7, '¬'),
+        (18, '€'),
+        (26, '['),
+        (27, ']'),
+        (40, '{'),
+        (41, '\\'),
+        (43, '}'),
+    ],
+    dead: &[26, 40],
+    keys: &[],
+};
+
+pub static LAYOUTS: [&Layout; 6] = [&US, &US_INTL, &UK, &DE, &FR, &ES];
+
+static KEYMAP: Param<&'static str> =
+    Param::new("keymap", "us", "Console keyboard layout: us, us-intl, uk, de, fr, es, or one in /etc/keymaps");
+
+// The layout chosen, once `keymap=` has been looked up
+static CHOSEN: Mutex<Option<&'static Layout>> = Mutex::new(None);
+// Layouts loaded from files. They are never freed, as the console may be
+// typing with one when another of the same name replaces it.
+static LOADED: Mutex<Vec<&'static Layout>> = Mutex::new(Vec::new());
+
+/// The layouts built in and loaded
+pub fn layouts() -> Vec<&'static Layout> {
+    LAYOUTS.iter().copied().chain(LOADED.lock().iter().copied()).collect()
+}
+
+/// A layout by name: built in, loaded, or else loaded now from
+/// `/etc/keymaps`
+pub fn find(name: &str) -> Option<&'static Layout> {
+    if let Some(layout) = layouts().into_iter().find(|layout| layout.name.eq_ignore_ascii_case(name)) {
+        return Some(layout);
+    }
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    load(&format!("{}/{}.map", KEYMAP_DIR, name)).ok()
+}
+
+/// The layout the console types with
+pub fn current() -> &'static Layout {
+    if let Some(layout) = *CHOSEN.lock() {
+        return layout;
+    }
+    let name = KEYMAP.get();
+    let layout = find(name).unwrap_or_else(|| {
+        crate::pr_warn!("keymap: no layout '{}', using us", name);
+        &US
+    });
+    *CHOSEN.lock() = Some(layout);
+    layout
+}
+
+pub fn set(layout: &'static Layout) {
+    *CHOSEN.lock() = Some(layout);
+}
+
+/// The character `accent` makes of `base`, if it goes on it
+pub fn compose(accent: char, base: char) -> Option<char> {
+    let &(_, bases, results) = COMPOSE.iter().find(|(accents, _, _)| accents.contains(accent))?;
+    let index = bases.chars().position(|c| c == base)?;
+    results.chars().nth(index)
+}
+
+fn leak(text: &str) -> &'static str {
+    Box::leak(String::from(text).into_boxed_str())
+}
+
+fn parse_symbol(word: &str) -> Result<Option<Keysym>, &'static str> {
+    if word == "-" {
+        return Ok(None);
+    }
+    if let Some(&(_, accent)) = DEAD_NAMES.iter().find(|&&(name, _)| name == word) {
+        return Ok(Some(Keysym::Dead(accent)));
+    }
+    if let Some(hex) = word.strip_prefix("U+") {
+        let code = u32::from_str_radix(hex, 16).map_err(|_| "bad U+ code")?;
+        return char::from_u32(code).map(|c| Some(Keysym::Char(c))).ok_or("U+ code is not a character");
+    }
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(Some(Keysym::Char(c))),
+        _ => Err("a symbol is one character, U+hex, a dead_ accent or -"),
+    }
+}
+
+/// Read a layout file
+pub fn parse(text: &str) -> Result<Layout, String> {
+    let (mut name, mut description) = (None, None);
+    let mut keys: Vec<(u16, [Option<Keysym>; 3])> = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let fail = |reason: &str| format!("line {}: {}", number + 1, reason);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match word {
+            "name" => name = Some(rest.trim()),
+            "description" => description = Some(rest.trim()),
+            "keycode" => {
+                let (code, symbols) = rest.split_once('=').ok_or_else(|| fail("keycode lines are keycode N = ..."))?;
+                let code: u16 = code.trim().parse().map_err(|_| fail("bad key code"))?;
+                if code == 0 || code > MAX_KEYCODE {
+                    return Err(fail("key code out of range"));
+                }
+                let mut levels = [None; 3];
+                for (index, word) in symbols.split_whitespace().enumerate() {
+                    let level = levels.get_mut(index).ok_or_else(|| fail("at most three symbols a key"))?;
+                    *level = parse_symbol(word).map_err(fail)?;
+                }
+                keys.retain(|&(known, _)| known != code);
+                keys.push((code, levels));
+            }
+            _ => return Err(fail("expected name, description or keycode")),
+        }
+    }
+
+    let name = name.filter(|name| !name.is_empty() && !name.contains(char::is_whitespace));
+    let name = name.ok_or("the layout needs a name, one word")?;
+    Ok(Layout {
+        name: leak(name),
+        description: leak(description.unwrap_or(name)),
+        rows: &US_ROWS,
+        altgr: &[],
+        dead: &[],
+        keys: Box::leak(keys.into_boxed_slice()),
+    })
+}
+
+/// Load a layout file, replacing a loaded layout of the same name
+pub fn load(path: &str) -> Result<&'static Layout, String> {
+    let data = crate::fs::vfs::VFS.lock().read_file(path).map_err(|error| format!("{}: {:?}", path, error))?;
+    let text = core::str::from_utf8(&data).map_err(|_| format!("{}: not UTF-8", path))?;
+    let layout = parse(text).map_err(|reason| format!("{}: {}", path, reason))?;
+    if LAYOUTS.iter().any(|builtin| builtin.name.eq_ignore_ascii_case(layout.name)) {
+        return Err(format!("{}: '{}' is built in", path, layout.name));
+    }
+
+    let mut loaded = LOADED.lock();
+    let same = loaded.iter().position(|known| known.name.eq_ignore_ascii_case(layout.name));
+    if same.is_none() && loaded.len() >= MAX_LOADED {
+        return Err(String::from("too many layouts loaded"));
+    }
+    let layout: &'static Layout = Box::leak(Box::new(layout));
+    match same {
+        Some(index) => loaded[index] = layout,
+        None => loaded.push(layout),
+    }
+    drop(loaded);
+
+    // The console types with the new one if it had the old
+    let mut chosen = CHOSEN.lock();
+    if chosen.is_some_and(|current| current.name.eq_ignore_ascii_case(layout.name)) {
+        *chosen = Some(layout);
+    }
+    Ok(layout)
+}
+
+impl Layout {
+    // What `code` types at a level, 0 alone, 1 with Shift and 2 with AltGr
+    fn symbol(&self, code: u16, level: usize) -> Option<Keysym> {
+        if let Some((_, levels)) = self.keys.iter().find(|&&(key, _)| key == code) {
+            return levels[level];
+        }
+        let character = match level {
+            2 => self.altgr.iter().find(|&&(key, _)| key == code).map(|&(_, character)| character),
+            _ => self.rows.iter().find_map(|&(plain, shifted, first)| {
+                let index = code.checked_sub(first)? as usize;
+                (if level == 1 { shifted } else { plain }).chars().nth(index)
+            }),
+        }?;
+        let accent = COMPOSE.iter().any(|(accents, _, _)| accents.contains(character));
+        Some(if accent && self.dead.contains(&code) { Keysym::Dead(character) } else { Keysym::Char(character) })
+    }
+
+    /// What `code` going down types
+    pub fn keysym(&self, code: u16, modifiers: Modifiers) -> Option<Keysym> {
+        if let Some(&(_, sequence)) = TERMINAL.iter().find(|&&(key, _)| key == code) {
+            return Some(Keysym::Terminal(sequence));
+        }
+        if modifiers.altgr {
+            return self.symbol(code, 2);
+        }
+
+        // Caps Lock shifts a key only where Shift makes a capital of it
+        let plain = self.symbol(code, 0);
+        let shifted = self.symbol(code, 1);
+        let letter = match (plain, shifted) {
+            (Some(Keysym::Char(plain)), Some(Keysym::Char(shifted))) => plain.is_lowercase() && shifted.is_uppercase(),
+            _ => false,
+        };
+        let symbol = if modifiers.shift != (modifiers.caps_lock && letter) { shifted } else { plain };
+
+        // Ctrl with a letter is its control character
+        match symbol {
+            Some(Keysym::Char(c)) if modifiers.ctrl && c.is_ascii_alphabetic() => {
+                Some(Keysym::Char((c.to_ascii_lowercase() as u8 - b'a' + 1) as char))
+            }
+            symbol => symbol,
+        }
+    }
+}
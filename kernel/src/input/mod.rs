@@ -0,0 +1,351 @@
+//! Input events
+//!
+//! Keyboards, mice, touchpads, gamepads and laptop hotkeys report here as Linux's
+//! evdev does: each event is a type, a code and a value, stamped with the
+//! time, and a SYN_REPORT closes a group that belongs together, such as
+//! one touchpad frame. Types and codes are Linux's, so
+//! `<linux/input-event-codes.h>` reads them. Each device has a node in
+//! `/dev/input`, see `evdev`, and events also wait in one shared queue for
+//! `read`, the oldest dropped when it fills. A device a handle has grabbed
+//! sends its events to that handle alone.
+//!
+//! Keys on keyboards repeat while held, after a delay, as value 2. What
+//! keyboards type goes to the shell through `console`, the layout in
+//! `keymap` and, when one is on, an input method from `ime`. Keys the
+//! kernel acts on itself, such as brightness and volume, also go to
+//! `hotkey`.
+
+pub mod console;
+pub mod evdev;
+pub mod gamepad;
+pub mod hid;
+pub mod hotkey;
+pub mod i2c_hid;
+pub mod ime;
+pub mod keymap;
+pub mod ps2;
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+
+pub const SYN_REPORT: u16 = 0x00;
+/// Events were lost before this one; see `evdev`
+pub const SYN_DROPPED: u16 = 0x03;
+/// The raw code of a key, reported before it
+pub const MSC_SCAN: u16 = 0x04;
+
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const ABS_X: u16 = 0x00;
+pub const ABS_Y: u16 = 0x01;
+pub const ABS_Z: u16 = 0x02;
+pub const ABS_RX: u16 = 0x03;
+pub const ABS_RY: u16 = 0x04;
+pub const ABS_RZ: u16 = 0x05;
+pub const ABS_HAT0X: u16 = 0x10;
+pub const ABS_HAT0Y: u16 = 0x11;
+pub const ABS_MT_SLOT: u16 = 0x2F;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+
+pub const KEY_ESC: u16 = 1;
+pub const KEY_BACKSPACE: u16 = 14;
+pub const KEY_TAB: u16 = 15;
+pub const KEY_ENTER: u16 = 28;
+pub const KEY_LEFTCTRL: u16 = 29;
+pub const KEY_LEFTSHIFT: u16 = 42;
+pub const KEY_RIGHTSHIFT: u16 = 54;
+pub const KEY_KPASTERISK: u16 = 55;
+pub const KEY_LEFTALT: u16 = 56;
+pub const KEY_SPACE: u16 = 57;
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const KEY_SCROLLLOCK: u16 = 70;
+pub const KEY_KPMINUS: u16 = 74;
+pub const KEY_KPPLUS: u16 = 78;
+pub const KEY_KPENTER: u16 = 96;
+pub const KEY_RIGHTCTRL: u16 = 97;
+pub const KEY_KPSLASH: u16 = 98;
+pub const KEY_RIGHTALT: u16 = 100;
+pub const KEY_HOME: u16 = 102;
+pub const KEY_UP: u16 = 103;
+pub const KEY_PAGEUP: u16 = 104;
+pub const KEY_LEFT: u16 = 105;
+pub const KEY_RIGHT: u16 = 106;
+pub const KEY_END: u16 = 107;
+pub const KEY_DOWN: u16 = 108;
+pub const KEY_PAGEDOWN: u16 = 109;
+pub const KEY_INSERT: u16 = 110;
+pub const KEY_DELETE: u16 = 111;
+pub const KEY_MUTE: u16 = 113;
+pub const KEY_VOLUMEDOWN: u16 = 114;
+pub const KEY_VOLUMEUP: u16 = 115;
+pub const KEY_LEFTMETA: u16 = 125;
+pub const KEY_RIGHTMETA: u16 = 126;
+pub const KEY_SLEEP: u16 = 142;
+pub const KEY_BRIGHTNESSDOWN: u16 = 224;
+pub const KEY_BRIGHTNESSUP: u16 = 225;
+pub const KEY_BLUETOOTH: u16 = 237;
+pub const KEY_WLAN: u16 = 238;
+pub const KEY_UNKNOWN: u16 = 240;
+pub const KEY_RFKILL: u16 = 247;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+// Gamepad buttons by where they sit: south is A on an Xbox pad and Cross
+// on a PlayStation one
+pub const BTN_SOUTH: u16 = 0x130;
+pub const BTN_EAST: u16 = 0x131;
+pub const BTN_NORTH: u16 = 0x133;
+pub const BTN_WEST: u16 = 0x134;
+pub const BTN_TL: u16 = 0x136;
+pub const BTN_TR: u16 = 0x137;
+pub const BTN_SELECT: u16 = 0x13A;
+pub const BTN_START: u16 = 0x13B;
+pub const BTN_MODE: u16 = 0x13C;
+pub const BTN_THUMBL: u16 = 0x13D;
+pub const BTN_THUMBR: u16 = 0x13E;
+pub const BTN_TOOL_FINGER: u16 = 0x145;
+pub const BTN_TOUCH: u16 = 0x14A;
+pub const BTN_TOOL_DOUBLETAP: u16 = 0x14D;
+
+/// Key names, as `hotkey map` takes them
+pub const KEY_NAMES: [(u16, &str); 9] = [
+    (KEY_MUTE, "mute"),
+    (KEY_VOLUMEDOWN, "volumedown"),
+    (KEY_VOLUMEUP, "volumeup"),
+    (KEY_SLEEP, "sleep"),
+    (KEY_BRIGHTNESSDOWN, "brightnessdown"),
+    (KEY_BRIGHTNESSUP, "brightnessup"),
+    (KEY_BLUETOOTH, "bluetooth"),
+    (KEY_WLAN, "wlan"),
+    (KEY_RFKILL, "rfkill"),
+];
+
+// Keys that only change what others type, and so do not repeat
+const MODIFIERS: [u16; 11] = [
+    KEY_LEFTCTRL,
+    KEY_RIGHTCTRL,
+    KEY_LEFTSHIFT,
+    KEY_RIGHTSHIFT,
+    KEY_LEFTALT,
+    KEY_RIGHTALT,
+    KEY_LEFTMETA,
+    KEY_RIGHTMETA,
+    KEY_CAPSLOCK,
+    KEY_NUMLOCK,
+    KEY_SCROLLLOCK,
+];
+
+// Events held before the oldest are dropped
+const QUEUE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub time_ns: u64,
+    pub device: usize,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Touchpad,
+    Mouse,
+    Keyboard,
+    Keys,
+    Hotkeys,
+    Gamepad,
+}
+
+impl DeviceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Touchpad => "touchpad",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Keys => "keys",
+            DeviceKind::Hotkeys => "hotkeys",
+            DeviceKind::Gamepad => "gamepad",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub kind: DeviceKind,
+    pub events: u64,
+    pub present: bool,
+}
+
+static DEVICES: Mutex<Vec<DeviceInfo>> = Mutex::new(Vec::new());
+static QUEUE: Mutex<VecDeque<InputEvent>> = Mutex::new(VecDeque::new());
+
+// Key repeat, in milliseconds: the wait before a held key repeats, and
+// between repeats, as Linux's defaults
+static REPEAT_RATE: Mutex<(u32, u32)> = Mutex::new((250, 33));
+
+// The key repeating, on which device, and when it next repeats
+#[derive(Debug, Clone, Copy)]
+struct Repeat {
+    device: usize,
+    code: u16,
+    next_ns: u64,
+}
+
+static REPEAT: Mutex<Option<Repeat>> = Mutex::new(None);
+
+/// Add a device, returning the number its events carry; numbers are not
+/// reused
+pub fn register(name: &str, kind: DeviceKind) -> usize {
+    let mut devices = DEVICES.lock();
+    let index = devices.len();
+    devices.push(DeviceInfo { index, name: String::from(name), kind, events: 0, present: true });
+    index
+}
+
+pub fn unregister(device: usize) {
+    if let Some(info) = DEVICES.lock().get_mut(device) {
+        info.present = false;
+    }
+    let mut repeat = REPEAT.lock();
+    if repeat.is_some_and(|repeat| repeat.device == device) {
+        *repeat = None;
+    }
+}
+
+// Start or stop a keyboard's key repeating
+fn track_repeat(event: &InputEvent) {
+    let mut repeat = REPEAT.lock();
+    match event.value {
+        1 if !MODIFIERS.contains(&event.code) => {
+            let delay_ns = REPEAT_RATE.lock().0 as u64 * 1_000_000;
+            *repeat = Some(Repeat { device: event.device, code: event.code, next_ns: event.time_ns + delay_ns });
+        }
+        0 if repeat.is_some_and(|repeat| repeat.device == event.device && repeat.code == event.code) => {
+            *repeat = None;
+        }
+        _ => {}
+    }
+}
+
+/// Queue an event
+pub fn report(device: usize, kind: u16, code: u16, value: i32) {
+    let event = InputEvent { time_ns: crate::time::clocksource::now_ns(), device, kind, code, value };
+    let keyboard = {
+        let mut devices = DEVICES.lock();
+        let info = devices.get_mut(device);
+        let keyboard = info.as_ref().is_some_and(|info| info.kind == DeviceKind::Keyboard);
+        if let Some(info) = info {
+            info.events += 1;
+        }
+        keyboard && kind == EV_KEY
+    };
+    if keyboard {
+        track_repeat(&event);
+    }
+    if evdev::deliver(&event) {
+        return;
+    }
+
+    {
+        let mut queue = QUEUE.lock();
+        if queue.len() == QUEUE_LENGTH {
+            queue.pop_front();
+        }
+        queue.push_back(event);
+    }
+    if kind == EV_KEY && value == 1 {
+        hotkey::key_pressed(code);
+    }
+    if keyboard {
+        console::key(code, value);
+    }
+}
+
+/// A key going down or up
+pub fn key(device: usize, code: u16, pressed: bool) {
+    report(device, EV_KEY, code, pressed as i32);
+}
+
+/// Close a group of events
+pub fn sync(device: usize) {
+    report(device, EV_SYN, SYN_REPORT, 0);
+}
+
+/// Take up to `max` events, oldest first
+pub fn read(max: usize) -> Vec<InputEvent> {
+    let mut queue = QUEUE.lock();
+    let count = queue.len().min(max);
+    queue.drain(..count).collect()
+}
+
+pub fn devices() -> Vec<DeviceInfo> {
+    DEVICES.lock().clone()
+}
+
+pub fn key_name(code: u16) -> Option<&'static str> {
+    KEY_NAMES.iter().find(|&&(known, _)| known == code).map(|&(_, name)| name)
+}
+
+pub fn key_code(name: &str) -> Option<u16> {
+    KEY_NAMES.iter().find(|&&(_, known)| known.eq_ignore_ascii_case(name)).map(|&(code, _)| code)
+}
+
+/// The key repeat delay and period, in milliseconds
+pub fn repeat_rate() -> (u32, u32) {
+    *REPEAT_RATE.lock()
+}
+
+/// Set the key repeat delay and period; a period of 0 stops keys
+/// repeating
+pub fn set_repeat_rate(delay_ms: u32, period_ms: u32) {
+    *REPEAT_RATE.lock() = (delay_ms, period_ms);
+}
+
+// Repeat the key held, if it is due
+fn repeat_keys() {
+    let due = {
+        let mut repeat = REPEAT.lock();
+        let period_ms = REPEAT_RATE.lock().1;
+        let now = crate::time::clocksource::now_ns();
+        match repeat.as_mut() {
+            Some(_) if period_ms == 0 => None,
+            Some(held) if now >= held.next_ns => {
+                held.next_ns = now + period_ms as u64 * 1_000_000;
+                Some((held.device, held.code))
+            }
+            _ => None,
+        }
+    };
+    if let Some((device, code)) = due {
+        report(device, EV_KEY, code, 2);
+        sync(device);
+    }
+}
+
+/// Read the devices that are polled, repeat held keys, stop timed rumble
+/// and type at the shell; called from the main loop
+pub fn poll() {
+    i2c_hid::poll();
+    repeat_keys();
+    gamepad::poll();
+    console::poll();
+}
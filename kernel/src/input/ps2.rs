@@ -0,0 +1,99 @@
+//! The PS/2 keyboard, as key events
+//!
+//! The i8042 hands over set 1 scan codes: a key's code as it goes down,
+//! and the same code with bit 7 set as it comes up. Up to 58h the codes
+//! are Linux's key codes. The keys the 101-key layout added come after an
+//! E0h byte and are looked up; the fake shifts sent around some of them
+//! are dropped, as is Pause's six byte sequence. A key held down is sent
+//! again by the keyboard; those codes are dropped too, as the input layer
+//! repeats keys itself. The main loop reads the port and passes each byte
+//! to `scancode`.
+
+use spin::Mutex;
+
+use super::{
+    DeviceKind, KEY_DELETE, KEY_DOWN, KEY_END, KEY_HOME, KEY_INSERT, KEY_KPENTER, KEY_KPSLASH, KEY_LEFT, KEY_PAGEDOWN,
+    KEY_PAGEUP, KEY_RIGHT, KEY_RIGHTALT, KEY_RIGHTCTRL, KEY_UP,
+};
+
+// The last code that is a key code as it is, F12's
+const LAST_PLAIN: u8 = 0x58;
+const EXTENDED: u8 = 0xE0;
+const PAUSE: u8 = 0xE1;
+// Bytes of Pause's sequence after the first
+const PAUSE_LENGTH: u8 = 5;
+
+// Codes that follow E0h, and their keys
+const EXTENDED_KEYS: [(u8, u16); 14] = [
+    (0x1C, KEY_KPENTER),
+    (0x1D, KEY_RIGHTCTRL),
+    (0x35, KEY_KPSLASH),
+    (0x38, KEY_RIGHTALT),
+    (0x47, KEY_HOME),
+    (0x48, KEY_UP),
+    (0x49, KEY_PAGEUP),
+    (0x4B, KEY_LEFT),
+    (0x4D, KEY_RIGHT),
+    (0x4F, KEY_END),
+    (0x50, KEY_DOWN),
+    (0x51, KEY_PAGEDOWN),
+    (0x52, KEY_INSERT),
+    (0x53, KEY_DELETE),
+];
+
+struct Keyboard {
+    device: Option<usize>,
+    extended: bool,
+    // Bytes of a Pause sequence still to come
+    skip: u8,
+    // The keys down, a bit for each
+    held: u128,
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard { device: None, extended: false, skip: 0, held: 0 });
+
+/// Register the keyboard as an input device, returning its number
+pub fn attach() -> usize {
+    *KEYBOARD.lock().device.get_or_insert_with(|| super::register("AT keyboard", DeviceKind::Keyboard))
+}
+
+/// Take a byte from the keyboard's data port
+pub fn scancode(byte: u8) {
+    let device = attach();
+    let (key, down) = {
+        let mut keyboard = KEYBOARD.lock();
+        if keyboard.skip > 0 {
+            keyboard.skip -= 1;
+            return;
+        }
+        match byte {
+            EXTENDED => {
+                keyboard.extended = true;
+                return;
+            }
+            PAUSE => {
+                keyboard.skip = PAUSE_LENGTH;
+                return;
+            }
+            _ => {}
+        }
+        let code = byte & 0x7F;
+        let key = if core::mem::replace(&mut keyboard.extended, false) {
+            EXTENDED_KEYS.iter().find(|&&(extended, _)| extended == code).map(|&(_, key)| key)
+        } else {
+            (code != 0 && code <= LAST_PLAIN).then_some(code as u16)
+        };
+        let Some(key) = key else {
+            return;
+        };
+        let (bit, down) = (1u128 << key, byte & 0x80 == 0);
+        if down && keyboard.held & bit != 0 {
+            return;
+        }
+        keyboard.held = if down { keyboard.held | bit } else { keyboard.held & !bit };
+        (key, down)
+    };
+
+    super::key(device, key, down);
+    super::sync(device);
+}
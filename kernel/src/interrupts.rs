@@ -1,7 +1,7 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::{self, Mutex};
+use crate::sync::Mutex;
 use crate::{println, serial_println};
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use core::sync::atomic::{AtomicU64, AtomicU32, Ordering};
@@ -114,8 +114,8 @@ pub use keyboard::read_key;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -170,7 +170,11 @@ pub static KEYBOARD_MODIFIERS: Mutex<KeyboardModifiers> = Mutex::new(KeyboardMod
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Breakpoints go through the probe handler, which needs the full
+        // register state
+        unsafe {
+            idt.breakpoint.set_handler_addr(crate::debug::kprobes::int3_entry());
+        }
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
@@ -180,6 +184,10 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        // Writes to watched kernel memory
+        idt.debug.set_handler_fn(debug_handler);
+        // Returns and indirect branches CET caught
+        idt.cp_protection_exception.set_handler_fn(control_protection_handler);
         
         // Add spurious interrupt handlers for both PICs
         idt[InterruptIndex::LPT1.as_usize()]
@@ -187,11 +195,11 @@ lazy_static! {
         idt[(PIC_2_OFFSET + 15) as usize]
             .set_handler_fn(spurious_interrupt_handler_pic2);
         
-        // Skip serial port handler - using polling instead
+        // Serial ports: COM1 and COM3 share IRQ 4, COM2 and COM4 IRQ 3
         idt[InterruptIndex::COM1.as_usize()]
-            .set_handler_fn(default_interrupt_handler);
+            .set_handler_fn(com1_interrupt_handler);
         idt[InterruptIndex::COM2.as_usize()]
-            .set_handler_fn(default_interrupt_handler);
+            .set_handler_fn(com2_interrupt_handler);
         
         // Network and disk interrupt handlers
         idt[(PIC_2_OFFSET + 1) as usize]
@@ -200,11 +208,27 @@ lazy_static! {
             .set_handler_fn(disk_interrupt_handler);
         idt[InterruptIndex::SecondaryATA.as_usize()]
             .set_handler_fn(disk_interrupt_handler);
+
+        // Performance counter overflows and watchdog requests
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+
+        // SynIC messages when running under Hyper-V
+        idt[crate::paravirt::SINT_VECTOR as usize]
+            .set_handler_fn(paravirt_interrupt_handler);
+
+        // High-resolution timer deadlines from the HPET
+        idt[crate::time::hpet::HPET_VECTOR as usize]
+            .set_handler_fn(hpet_interrupt_handler);
         
         idt
     };
 }
 
+/// Where the gate for `vector` is in the IDT
+pub fn idt_gate_address(vector: u8) -> u64 {
+    &*IDT as *const InterruptDescriptorTable as u64 + vector as u64 * 16
+}
+
 pub fn init_idt() {
     IDT.load();
     
@@ -262,6 +286,10 @@ pub fn set_interrupt_priority(vector: u8, priority: InterruptPriority) {
 }
 
 pub fn send_eoi_apic() {
+    // KVM may have completed it already
+    if crate::paravirt::eoi() {
+        return;
+    }
     unsafe {
         let apic_ptr = APIC_BASE_ADDR as *mut u32;
         let eoi = apic_ptr.add((APIC_EOI / 4) as usize);
@@ -275,12 +303,6 @@ pub fn init_keyboard() {
     *KEYBOARD.lock() = Some(keyboard);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
-{
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
 extern "x86-interrupt" fn default_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
@@ -290,29 +312,25 @@ extern "x86-interrupt" fn default_interrupt_handler(
     }
 }
 
-extern "x86-interrupt" fn serial_interrupt_handler(
+extern "x86-interrupt" fn com1_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    // Read and process serial input
-    while let Some(byte) = crate::serial::read_byte() {
-        // Convert byte to char and send to keyboard handler
-        if let Some(handler) = *KEYBOARD_HANDLER.lock() {
-            // Handle special characters
-            match byte {
-                0x0D => handler('\n'), // Carriage return -> newline
-                0x08 | 0x7F => handler('\x08'), // Backspace/Delete
-                b if b.is_ascii() => handler(byte as char),
-                _ => {} // Ignore non-ASCII
-            }
-        }
-    }
-    
-    // Send EOI to the PICs
+    // Received bytes are queued for the console or /dev/ttyS*
+    crate::serial::handle_irq(InterruptIndex::COM1.as_u8() - PIC_1_OFFSET);
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::COM1.as_u8());
     }
 }
 
+extern "x86-interrupt" fn com2_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    crate::serial::handle_irq(InterruptIndex::COM2.as_u8() - PIC_1_OFFSET);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::COM2.as_u8());
+    }
+}
+
 extern "x86-interrupt" fn spurious_interrupt_handler_pic1(
     _stack_frame: InterruptStackFrame)
 {
@@ -330,13 +348,49 @@ extern "x86-interrupt" fn spurious_interrupt_handler_pic2(
     serial_println!("Spurious interrupt from PIC2");
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    // No locks here: the NMI may have interrupted their holder on this CPU
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
+    let counters = crate::perf::handle_overflow_nmi(&stack_frame, frame_pointer);
+    let watchdog = crate::debug::watchdog::handle_nmi(&stack_frame, frame_pointer);
+    if !counters && !watchdog {
+        crate::klog::unlock_for_nmi();
+        serial_println!("NMI received for unknown reason at {:#x}", stack_frame.instruction_pointer.as_u64());
+    }
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
+    crate::security::watchpoints::handle_debug(&stack_frame, frame_pointer);
+}
+
+extern "x86-interrupt" fn control_protection_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
+    crate::security::mitigations::handle_control_protection(&stack_frame, error_code, frame_pointer);
+}
+
+extern "x86-interrupt" fn paravirt_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // SynIC messages from the hypervisor; the SINT is auto-EOI, so no EOI here
+    crate::sync::lockdep::hardirq_enter();
+    crate::paravirt::handle_interrupt();
+    crate::sync::lockdep::hardirq_exit();
+}
+
+extern "x86-interrupt" fn hpet_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    send_eoi_apic();
+    crate::sync::lockdep::hardirq_enter();
+    crate::time::hrtimer::run_expired();
+    crate::sync::lockdep::hardirq_exit();
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, error_code: u64) -> !
 {
     // Double fault is critical - try to save as much info as possible
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
     serial_println!("\n=== CRITICAL: DOUBLE FAULT EXCEPTION ===");
     serial_println!("Error Code: {:#x}", error_code);
-    serial_println!("Stack Frame: {:#?}", stack_frame);
+    crate::debug::oops("double fault", &stack_frame, frame_pointer);
     
     // Try to get more CPU state info
     let cr2: u64;
@@ -351,14 +405,21 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("EXCEPTION: DOUBLE FAULT - System cannot recover");
 }
 
+crate::declare_trace_event!(pub TRACE_IRQ_ENTRY, "irq", "irq_entry", ["vector"]);
+crate::declare_trace_event!(pub TRACE_IRQ_EXIT, "irq", "irq_exit", ["vector"]);
+
 // Global timer tick counter
 pub static TIMER_TICKS: Mutex<u64> = Mutex::new(0);
 
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    stack_frame: InterruptStackFrame)
 {
     // Track interrupt latency
     let start_cycles = crate::timer::rdtsc();
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
+    TRACE_IRQ_ENTRY.emit(&[InterruptIndex::Timer.as_u8() as u64]);
+    crate::smp::cpustat::tick(stack_frame.code_segment & 3 == 3, crate::sync::lockdep::in_hardirq());
+    crate::sync::lockdep::hardirq_enter();
     // Send EOI first to prevent interrupt stacking
     if is_apic_available() {
         send_eoi_apic();
@@ -369,6 +430,12 @@ extern "x86-interrupt" fn timer_interrupt_handler(
         }
     }
     
+    crate::crypto::rng::add_interrupt_entropy(InterruptIndex::Timer.as_u8());
+    crate::perf::sampler::timer_tick(&stack_frame, frame_pointer);
+    crate::debug::watchdog::heartbeat(&stack_frame, frame_pointer);
+    crate::power::shutdown::watchdog_tick();
+    crate::security::watchpoints::tick();
+
     // Increment timer tick counter
     let ticks = {
         let mut counter = TIMER_TICKS.lock();
@@ -380,6 +447,9 @@ extern "x86-interrupt" fn timer_interrupt_handler(
     if let Some(mut timer) = crate::timer::TIMER.try_lock() {
         timer.tick();
     }
+
+    // Catches high-resolution timers when the HPET cannot interrupt
+    crate::time::hrtimer::run_expired();
     
     // Call process scheduler every 10 ticks, but use try_lock to avoid deadlocks
     if ticks % 10 == 0 {  // Schedule every 10 ticks
@@ -391,7 +461,9 @@ extern "x86-interrupt" fn timer_interrupt_handler(
         // If we can't get the lock, skip this scheduling tick
     }
     
+    crate::sync::lockdep::hardirq_exit();
     // Update interrupt statistics
+    TRACE_IRQ_EXIT.emit(&[InterruptIndex::Timer.as_u8() as u64]);
     let end_cycles = crate::timer::rdtsc();
     let latency = end_cycles - start_cycles;
     let stats = &INTERRUPT_STATS[InterruptIndex::Timer.as_usize()];
@@ -405,12 +477,15 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     let start_cycles = crate::timer::rdtsc();
+    TRACE_IRQ_ENTRY.emit(&[InterruptIndex::Keyboard.as_u8() as u64]);
+    crate::sync::lockdep::hardirq_enter();
     use x86_64::instructions::port::Port;
     use pc_keyboard::{KeyCode, KeyState};
     
     // Read scancode immediately to clear the keyboard buffer
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
+    crate::crypto::rng::add_interrupt_entropy(scancode);
     
     // Send EOI early to prevent interrupt stacking
     if is_apic_available() {
@@ -456,6 +531,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
                             character = character.to_ascii_lowercase();
                         }
                         
+                        // Feed the console host input queue
+                        crate::win32::console::queue_key(character, 0, console_key_state(&modifiers));
+                        
                         // Handle Ctrl combinations
                         if modifiers.ctrl {
                             match character {
@@ -499,8 +577,13 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
                     },
                     DecodedKey::RawKey(key) => {
                         // Handle special keys with modifiers
+                        // The modifier guard taken above is still held;
+                        // locking it again here would spin forever
                         use pc_keyboard::KeyCode;
-                        let modifiers = KEYBOARD_MODIFIERS.lock();
+                        
+                        if let Some(vk) = raw_key_to_virtual_key(key) {
+                            crate::win32::console::queue_key('\0', vk, console_key_state(&modifiers));
+                        }
                         
                         match key {
                             KeyCode::F1 => {
@@ -540,7 +623,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
             }
         }
     }
+    crate::sync::lockdep::hardirq_exit();
     // Update interrupt statistics
+    TRACE_IRQ_EXIT.emit(&[InterruptIndex::Keyboard.as_u8() as u64]);
     let end_cycles = crate::timer::rdtsc();
     let latency = end_cycles - start_cycles;
     let stats = &INTERRUPT_STATS[InterruptIndex::Keyboard.as_usize()];
@@ -550,6 +635,36 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     // EOI already sent at the beginning of the handler
 }
 
+// Console control key state for the current modifiers
+fn console_key_state(modifiers: &KeyboardModifiers) -> u32 {
+    use crate::win32::console::{CAPSLOCK_ON, LEFT_ALT_PRESSED, LEFT_CTRL_PRESSED, SHIFT_PRESSED};
+    
+    let mut state = 0;
+    if modifiers.shift { state |= SHIFT_PRESSED; }
+    if modifiers.ctrl { state |= LEFT_CTRL_PRESSED; }
+    if modifiers.alt { state |= LEFT_ALT_PRESSED; }
+    if modifiers.caps_lock { state |= CAPSLOCK_ON; }
+    state
+}
+
+// Map non-character keys to Win32 virtual key codes
+fn raw_key_to_virtual_key(key: pc_keyboard::KeyCode) -> Option<u16> {
+    use pc_keyboard::KeyCode;
+    use crate::win32::console::*;
+    
+    match key {
+        KeyCode::ArrowUp => Some(VK_UP),
+        KeyCode::ArrowDown => Some(VK_DOWN),
+        KeyCode::ArrowLeft => Some(VK_LEFT),
+        KeyCode::ArrowRight => Some(VK_RIGHT),
+        KeyCode::Home => Some(VK_HOME),
+        KeyCode::End => Some(VK_END),
+        KeyCode::Insert => Some(VK_INSERT),
+        KeyCode::Delete => Some(VK_DELETE),
+        _ => None,
+    }
+}
+
 // Keyboard input handler callback
 type KeyboardHandler = fn(char);
 pub static KEYBOARD_HANDLER: Mutex<Option<KeyboardHandler>> = Mutex::new(None);
@@ -567,6 +682,7 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let frame_pointer = crate::debug::unwind::interrupted_frame_pointer();
     let addr = Cr2::read();
     
     // Check if this is a stack overflow
@@ -579,6 +695,7 @@ extern "x86-interrupt" fn page_fault_handler(
         serial_println!("Stack Pointer: {:#x}", rsp);
         serial_println!("Fault Address: {:#x}", fault_addr);
         serial_println!("Instruction Pointer: {:#x}", stack_frame.instruction_pointer.as_u64());
+        crate::debug::oops("stack overflow", &stack_frame, frame_pointer);
         println!("\n=== STACK OVERFLOW DETECTED ===");
         println!("Stack exhausted at address: {:#x}", fault_addr);
         panic!("Stack overflow - increase stack size or reduce recursion");
@@ -595,6 +712,7 @@ extern "x86-interrupt" fn page_fault_handler(
         serial_println!("  User mode: {}", error_code.contains(PageFaultErrorCode::USER_MODE));
         serial_println!("  Instruction fetch: {}", error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH));
         serial_println!("Handler Error: {}", e);
+        crate::debug::oops("unhandled page fault", &stack_frame, frame_pointer);
         
         println!("EXCEPTION: PAGE FAULT");
         println!("Accessed Address: {:?}", addr);
@@ -650,6 +768,8 @@ extern "x86-interrupt" fn network_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     let start_cycles = crate::timer::rdtsc();
+    TRACE_IRQ_ENTRY.emit(&[(PIC_2_OFFSET + 1) as u64]);
+    crate::sync::lockdep::hardirq_enter();
     
     if !NETWORK_COALESCER.should_handle() {
         // Skip this interrupt, will be handled in batch
@@ -660,6 +780,8 @@ extern "x86-interrupt" fn network_interrupt_handler(
                 PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 1);
             }
         }
+        crate::sync::lockdep::hardirq_exit();
+        TRACE_IRQ_EXIT.emit(&[(PIC_2_OFFSET + 1) as u64]);
         return;
     }
     
@@ -667,6 +789,7 @@ extern "x86-interrupt" fn network_interrupt_handler(
     process_network_packets();
     
     // Update interrupt statistics
+    TRACE_IRQ_EXIT.emit(&[(PIC_2_OFFSET + 1) as u64]);
     let end_cycles = crate::timer::rdtsc();
     let latency = end_cycles - start_cycles;
     let stats = &INTERRUPT_STATS[(PIC_2_OFFSET + 1) as usize];
@@ -682,6 +805,7 @@ extern "x86-interrupt" fn network_interrupt_handler(
             PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 1);
         }
     }
+    crate::sync::lockdep::hardirq_exit();
 }
 
 // Disk interrupt handler with coalescing
@@ -689,6 +813,8 @@ extern "x86-interrupt" fn disk_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     let start_cycles = crate::timer::rdtsc();
+    TRACE_IRQ_ENTRY.emit(&[InterruptIndex::PrimaryATA.as_u8() as u64]);
+    crate::sync::lockdep::hardirq_enter();
     
     if !DISK_COALESCER.should_handle() {
         // Skip this interrupt, will be handled in batch
@@ -699,6 +825,8 @@ extern "x86-interrupt" fn disk_interrupt_handler(
                 PICS.lock().notify_end_of_interrupt(InterruptIndex::PrimaryATA.as_u8());
             }
         }
+        crate::sync::lockdep::hardirq_exit();
+        TRACE_IRQ_EXIT.emit(&[InterruptIndex::PrimaryATA.as_u8() as u64]);
         return;
     }
     
@@ -706,6 +834,7 @@ extern "x86-interrupt" fn disk_interrupt_handler(
     process_disk_operations();
     
     // Update interrupt statistics
+    TRACE_IRQ_EXIT.emit(&[InterruptIndex::PrimaryATA.as_u8() as u64]);
     let end_cycles = crate::timer::rdtsc();
     let latency = end_cycles - start_cycles;
     let stats = &INTERRUPT_STATS[InterruptIndex::PrimaryATA.as_usize()];
@@ -721,6 +850,7 @@ extern "x86-interrupt" fn disk_interrupt_handler(
             PICS.lock().notify_end_of_interrupt(InterruptIndex::PrimaryATA.as_u8());
         }
     }
+    crate::sync::lockdep::hardirq_exit();
 }
 
 // Network packet queue for interrupt processing
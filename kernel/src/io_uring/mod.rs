@@ -0,0 +1,461 @@
+//! io_uring-style asynchronous I/O
+//!
+//! A process sets up a ring with `io_uring_setup` and maps it through the
+//! descriptor it gets back: a header, the submission queue and the
+//! completion queue, in one object shared with the kernel. It fills in
+//! submission queue entries, moves the SQ tail and calls `io_uring_enter`,
+//! which runs what was submitted and posts a completion for each, carrying
+//! the entry's `user_data`. One call takes a whole batch, and completions
+//! are read straight from the mapping without another.
+//!
+//! Entries and completions are laid out as on Linux and the opcodes have
+//! Linux's numbers, but the mapping is simpler: one object, and no array of
+//! indices between the SQ and its entries. `Params` gives the offsets.
+//!
+//! Operations run in the caller's context during `io_uring_enter`. One
+//! that cannot go ahead yet, such as a receive with nothing received or an
+//! accept with no connection waiting, stays pending and is tried again on
+//! every later enter and while the caller waits for completions. The wait
+//! sleeps between tries; a ring set up with IORING_SETUP_IOPOLL spins
+//! instead, for servers that would rather spend a CPU than the wake-up.
+//!
+//! At most as many operations as the CQ has entries are in flight at once,
+//! so none is ever dropped: completions the CQ has no room for wait in the
+//! kernel, and further submissions fail with EBUSY until they are reaped.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::VirtAddr;
+
+use crate::fs::file_ops;
+use crate::memory::page_cache::{self, FileKey};
+use crate::memory::userspace::validate_user_buffer;
+use crate::net::stream;
+use crate::sync::Mutex;
+use crate::syscall::handlers::{self, file_errno};
+use crate::syscall::{EAGAIN, EBADF, EBUSY, EFAULT, EINVAL, EIO, EMFILE, ENOTSOCK, ESPIPE};
+use crate::time::clocksource::now_ns;
+
+/// Busy-wait for completions instead of sleeping
+pub const IORING_SETUP_IOPOLL: u32 = 1 << 0;
+/// Size the CQ from `Params::cq_entries` rather than twice the SQ
+pub const IORING_SETUP_CQSIZE: u32 = 1 << 3;
+
+/// Wait for `min_complete` completions before returning
+pub const IORING_ENTER_GETEVENTS: usize = 1 << 0;
+
+pub const IORING_OP_NOP: u8 = 0;
+pub const IORING_OP_FSYNC: u8 = 3;
+pub const IORING_OP_ACCEPT: u8 = 13;
+pub const IORING_OP_READ: u8 = 22;
+pub const IORING_OP_WRITE: u8 = 23;
+pub const IORING_OP_SEND: u8 = 26;
+pub const IORING_OP_RECV: u8 = 27;
+
+/// A READ or WRITE offset meaning the descriptor's own position, which
+/// also lets them work on streams and serial ports
+pub const CURRENT_POSITION: u64 = u64::MAX;
+
+const MAX_ENTRIES: u32 = 4096;
+
+// Per-process limit on rings
+const MAX_RINGS: usize = 8;
+
+// Clear of /dev/kvm's handles, the serial ports, streams and files
+const FIRST_FD: usize = 384;
+
+// The header's fields, as byte offsets. The kernel moves the SQ head and
+// CQ tail, the process the SQ tail and CQ head.
+const SQ_HEAD: u64 = 0;
+const SQ_TAIL: u64 = 4;
+const SQ_MASK: u64 = 8;
+const SQ_ENTRIES: u64 = 12;
+const CQ_HEAD: u64 = 16;
+const CQ_TAIL: u64 = 20;
+const CQ_MASK: u64 = 24;
+const CQ_ENTRIES: u64 = 28;
+const HEADER_SIZE: u64 = 64;
+
+const SQE_SIZE: u64 = 64;
+const CQE_SIZE: u64 = 16;
+
+// Between tries while waiting for completions
+const WAIT_STEP_NS: u64 = 100_000;
+
+/// Where the SQ's fields and entries are in the mapping
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SqOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub sqes: u32,
+}
+
+/// Where the CQ's fields and completions are in the mapping
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CqOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub cqes: u32,
+}
+
+/// io_uring_setup's argument: `flags`, and `cq_entries` with
+/// IORING_SETUP_CQSIZE, go in and the rest comes back
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Params {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: u32,
+    /// Bytes to map from the ring's descriptor, with MAP_SHARED
+    pub ring_size: u32,
+    pub sq_off: SqOffsets,
+    pub cq_off: CqOffsets,
+}
+
+// The parts of a submission queue entry that are used
+#[derive(Debug, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    user_data: u64,
+}
+
+impl Sqe {
+    fn parse(bytes: &[u8; SQE_SIZE as usize]) -> Sqe {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        Sqe {
+            opcode: bytes[0],
+            flags: bytes[1],
+            fd: u32_at(4) as i32,
+            off: u64_at(8),
+            addr: u64_at(16),
+            len: u32_at(24),
+            user_data: u64_at(32),
+        }
+    }
+}
+
+struct Ring {
+    object: FileKey,
+    flags: u32,
+    sq_entries: u32,
+    cq_entries: u32,
+    // The kernel's own copies of the indices it moves; the mapping's are
+    // only written
+    sq_head: u32,
+    cq_tail: u32,
+    // Submitted, waiting until they can run
+    pending: Vec<Sqe>,
+    // Done, waiting for room in the CQ, as (user_data, res)
+    completed: VecDeque<(u64, i32)>,
+}
+
+impl Ring {
+    fn load(&self, field: u64) -> Result<u32, usize> {
+        let mut bytes = [0; 4];
+        page_cache::read(&self.object, field, &mut bytes).map_err(|_| EIO)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn store(&self, field: u64, value: u32) -> Result<(), usize> {
+        page_cache::write(&self.object, field, &value.to_le_bytes()).map_err(|_| EIO)?;
+        Ok(())
+    }
+
+    fn cqes(&self) -> u64 {
+        HEADER_SIZE + self.sq_entries as u64 * SQE_SIZE
+    }
+
+    fn in_flight(&self) -> usize {
+        self.pending.len() + self.completed.len()
+    }
+
+    // Run an entry, or keep it for later if it cannot go ahead yet
+    fn start(&mut self, sqe: Sqe) {
+        let res = match execute(&sqe) {
+            Err(EAGAIN) => {
+                self.pending.push(sqe);
+                return;
+            }
+            Ok(count) => count.min(i32::MAX as usize) as i32,
+            Err(errno) => -(errno as i32),
+        };
+        self.completed.push_back((sqe.user_data, res));
+    }
+
+    // Take up to `count` entries off the SQ and start them
+    fn submit(&mut self, count: usize) -> Result<usize, usize> {
+        let queued = self.load(SQ_TAIL)?.wrapping_sub(self.sq_head).min(self.sq_entries) as usize;
+        let room = self.cq_entries as usize - self.in_flight();
+        let count = count.min(queued);
+        if count > 0 && room == 0 {
+            return Err(EBUSY);
+        }
+        for _ in 0..count.min(room) {
+            let index = (self.sq_head & (self.sq_entries - 1)) as u64;
+            let mut bytes = [0; SQE_SIZE as usize];
+            page_cache::read(&self.object, HEADER_SIZE + index * SQE_SIZE, &mut bytes).map_err(|_| EIO)?;
+            self.sq_head = self.sq_head.wrapping_add(1);
+            self.start(Sqe::parse(&bytes));
+        }
+        self.store(SQ_HEAD, self.sq_head)?;
+        Ok(count.min(room))
+    }
+
+    fn retry_pending(&mut self) {
+        for sqe in core::mem::take(&mut self.pending) {
+            self.start(sqe);
+        }
+    }
+
+    // Post what fits of the completions, moving the CQ tail once
+    fn flush(&mut self) -> Result<(), usize> {
+        let head = self.load(CQ_HEAD)?;
+        let mut posted = false;
+        while self.cq_tail.wrapping_sub(head) < self.cq_entries {
+            let Some((user_data, res)) = self.completed.pop_front() else {
+                break;
+            };
+            let mut cqe = [0; CQE_SIZE as usize];
+            cqe[..8].copy_from_slice(&user_data.to_le_bytes());
+            cqe[8..12].copy_from_slice(&res.to_le_bytes());
+            let index = (self.cq_tail & (self.cq_entries - 1)) as u64;
+            page_cache::write(&self.object, self.cqes() + index * CQE_SIZE, &cqe).map_err(|_| EIO)?;
+            self.cq_tail = self.cq_tail.wrapping_add(1);
+            posted = true;
+        }
+        if posted {
+            self.store(CQ_TAIL, self.cq_tail)?;
+        }
+        Ok(())
+    }
+
+    // Completions posted and not yet reaped
+    fn ready(&self) -> Result<usize, usize> {
+        Ok(self.cq_tail.wrapping_sub(self.load(CQ_HEAD)?) as usize)
+    }
+
+    // Let the network and timers run, then pause before the next try
+    fn wait_step(&self) {
+        crate::net::interface::poll();
+        crate::net::tcp::poll_timers();
+        if self.flags & IORING_SETUP_IOPOLL != 0 {
+            core::hint::spin_loop();
+        } else {
+            crate::time::hrtimer::sleep_ns(WAIT_STEP_NS);
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        page_cache::release_anonymous(&self.object);
+    }
+}
+
+fn fd_of(sqe: &Sqe) -> Result<usize, usize> {
+    usize::try_from(sqe.fd).map_err(|_| EBADF)
+}
+
+fn user_buffer(sqe: &Sqe) -> Result<&'static mut [u8], usize> {
+    let addr = VirtAddr::try_new(sqe.addr).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, sqe.len as usize) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(sqe.addr as *mut u8, sqe.len as usize) })
+}
+
+// Reads and writes at an offset are for files only
+fn file_fd(sqe: &Sqe) -> Result<i32, usize> {
+    let fd = fd_of(sqe)?;
+    if !file_ops::owns(fd) {
+        return Err(ESPIPE);
+    }
+    Ok(fd as i32)
+}
+
+fn stream_fd(sqe: &Sqe) -> Result<usize, usize> {
+    let fd = fd_of(sqe)?;
+    if !stream::owns(fd) {
+        return Err(ENOTSOCK);
+    }
+    Ok(fd)
+}
+
+// Run one entry: a count on success, EAGAIN if it cannot go ahead yet
+fn execute(sqe: &Sqe) -> Result<usize, usize> {
+    // No linking, draining or fixed files
+    if sqe.flags != 0 {
+        return Err(EINVAL);
+    }
+    let (addr, len) = (sqe.addr as usize, sqe.len as usize);
+    match sqe.opcode {
+        IORING_OP_NOP => Ok(0),
+        IORING_OP_READ if sqe.off == CURRENT_POSITION => handlers::sys_read(fd_of(sqe)?, addr, len),
+        IORING_OP_READ => file_ops::sys_pread(file_fd(sqe)?, user_buffer(sqe)?, sqe.off).map_err(file_errno),
+        IORING_OP_WRITE if sqe.off == CURRENT_POSITION => handlers::sys_write(fd_of(sqe)?, addr, len),
+        IORING_OP_WRITE => file_ops::sys_pwrite(file_fd(sqe)?, user_buffer(sqe)?, sqe.off).map_err(file_errno),
+        IORING_OP_FSYNC => {
+            let fd = fd_of(sqe)?;
+            if !file_ops::owns(fd) {
+                return Err(EINVAL);
+            }
+            file_ops::sys_fsync(fd as i32).map(|()| 0).map_err(file_errno)
+        }
+        IORING_OP_ACCEPT => stream::accept(stream_fd(sqe)?),
+        IORING_OP_SEND => stream::write(stream_fd(sqe)?, user_buffer(sqe)?),
+        IORING_OP_RECV => stream::read(stream_fd(sqe)?, user_buffer(sqe)?),
+        _ => Err(EINVAL),
+    }
+}
+
+// Ring by (process, fd)
+static RINGS: Mutex<BTreeMap<(u32, usize), Arc<Mutex<Ring>>>> = Mutex::new(BTreeMap::new());
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+fn ring(fd: usize) -> Result<Arc<Mutex<Ring>>, usize> {
+    RINGS.lock().get(&(current_pid(), fd)).cloned().ok_or(EBADF)
+}
+
+/// Whether `fd` is a ring the caller has open
+pub fn owns(fd: usize) -> bool {
+    RINGS.lock().contains_key(&(current_pid(), fd))
+}
+
+/// The object behind one of the caller's rings, for mmap
+pub fn mappable(fd: usize) -> Option<FileKey> {
+    ring(fd).ok().map(|ring| ring.lock().object.clone())
+}
+
+/// Set up a ring of `entries` submission queue entries, rounded up to a
+/// power of two, and fill in `params`
+pub fn setup(entries: usize, params: usize) -> Result<usize, usize> {
+    let addr = VirtAddr::try_new(params as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, size_of::<Params>()) {
+        return Err(EFAULT);
+    }
+    let mut p = unsafe { core::ptr::read_unaligned(params as *const Params) };
+    if entries == 0 || entries > MAX_ENTRIES as usize || p.flags & !(IORING_SETUP_IOPOLL | IORING_SETUP_CQSIZE) != 0 {
+        return Err(EINVAL);
+    }
+    let sq_entries = (entries as u32).next_power_of_two();
+    let cq_entries = if p.flags & IORING_SETUP_CQSIZE != 0 {
+        if p.cq_entries < sq_entries || p.cq_entries > 2 * MAX_ENTRIES {
+            return Err(EINVAL);
+        }
+        p.cq_entries.next_power_of_two()
+    } else {
+        2 * sq_entries
+    };
+    let ring_size = HEADER_SIZE + sq_entries as u64 * SQE_SIZE + cq_entries as u64 * CQE_SIZE;
+
+    let pid = current_pid();
+    let mut rings = RINGS.lock();
+    let used: Vec<usize> = rings.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_RINGS {
+        return Err(EMFILE);
+    }
+    let ring = Ring {
+        object: page_cache::create_anonymous(ring_size),
+        flags: p.flags,
+        sq_entries,
+        cq_entries,
+        sq_head: 0,
+        cq_tail: 0,
+        pending: Vec::new(),
+        completed: VecDeque::new(),
+    };
+    ring.store(SQ_MASK, sq_entries - 1)?;
+    ring.store(SQ_ENTRIES, sq_entries)?;
+    ring.store(CQ_MASK, cq_entries - 1)?;
+    ring.store(CQ_ENTRIES, cq_entries)?;
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    rings.insert((pid, fd), Arc::new(Mutex::new(ring)));
+    drop(rings);
+
+    p.sq_entries = sq_entries;
+    p.cq_entries = cq_entries;
+    p.ring_size = ring_size as u32;
+    p.sq_off = SqOffsets {
+        head: SQ_HEAD as u32,
+        tail: SQ_TAIL as u32,
+        ring_mask: SQ_MASK as u32,
+        ring_entries: SQ_ENTRIES as u32,
+        sqes: HEADER_SIZE as u32,
+    };
+    p.cq_off = CqOffsets {
+        head: CQ_HEAD as u32,
+        tail: CQ_TAIL as u32,
+        ring_mask: CQ_MASK as u32,
+        ring_entries: CQ_ENTRIES as u32,
+        cqes: (ring_size - cq_entries as u64 * CQE_SIZE) as u32,
+    };
+    unsafe { core::ptr::write_unaligned(params as *mut Params, p) };
+    Ok(fd)
+}
+
+/// Submit up to `to_submit` entries and, with IORING_ENTER_GETEVENTS, wait
+/// until `min_complete` completions are ready or `timeout_ms` has passed,
+/// 0 waiting as long as it takes. Returns how many entries were taken.
+pub fn enter(
+    fd: usize,
+    to_submit: usize,
+    min_complete: usize,
+    flags: usize,
+    timeout_ms: usize,
+) -> Result<usize, usize> {
+    if flags & !IORING_ENTER_GETEVENTS != 0 {
+        return Err(EINVAL);
+    }
+    let ring = ring(fd)?;
+    // One thread at a time runs a ring's entries
+    let Some(mut ring) = ring.try_lock() else {
+        return Err(EBUSY);
+    };
+    ring.retry_pending();
+    let submitted = ring.submit(to_submit)?;
+    ring.flush()?;
+    if flags & IORING_ENTER_GETEVENTS != 0 {
+        let deadline = match timeout_ms {
+            0 => u64::MAX,
+            ms => now_ns().saturating_add(ms as u64 * 1_000_000),
+        };
+        while ring.ready()? < min_complete && now_ns() < deadline {
+            ring.wait_step();
+            ring.retry_pending();
+            ring.flush()?;
+        }
+    }
+    Ok(submitted)
+}
+
+/// Close a ring; false if `fd` is not one of ours. Its object stays while
+/// it is mapped.
+pub fn close(fd: usize) -> bool {
+    RINGS.lock().remove(&(current_pid(), fd)).is_some()
+}
+
+/// Close every ring an exiting process has open
+pub fn release_process(pid: u32) {
+    let mut rings = RINGS.lock();
+    let fds: Vec<usize> = rings.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    for fd in fds {
+        rings.remove(&(pid, fd));
+    }
+}
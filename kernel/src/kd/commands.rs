@@ -459,10 +459,11 @@ fn display_registers() {
 }
 
 fn display_call_stack(depth: usize) {
+    let frames = crate::debug::unwind::current_frames(depth);
     crate::println!("Call stack:");
-    crate::println!("  # RetAddr           Call Site");
-    for i in 0..depth {
-        crate::println!("  {} ffffffff8000{:04x} rust_kernel+0x{:04x}",
-            i, 1000 + i * 8, 1000 + i * 8);
+    crate::println!("  # Child-SP          RetAddr           Call Site");
+    for (i, frame) in frames.iter().enumerate() {
+        crate::println!("  {:02x} {:016x} {:016x} {}",
+            i, frame.sp, frame.pc, frame.symbol());
     }
 }
\ No newline at end of file
@@ -107,7 +107,14 @@ impl KernelDebugger {
         // Initialize transport based on connection type
         match connection_type {
             ConnectionType::Serial => {
+                // The transport being replaced lets go of its port first
                 self.transport = DebugTransport::Serial(protocol::SerialTransport::new());
+                if let DebugTransport::Serial(transport) = &mut self.transport {
+                    if !transport.claim() {
+                        crate::serial_println!("KD: Serial port unavailable or in use");
+                        return false;
+                    }
+                }
             }
             ConnectionType::Network => {
                 self.transport = DebugTransport::Network(protocol::NetworkTransport::new());
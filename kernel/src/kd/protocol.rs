@@ -100,16 +100,32 @@ pub struct DebugIo {
 
 // Transport implementations
 pub struct SerialTransport {
-    port: u16,
+    port: usize,  // ttyS number
     baud_rate: u32,
+    claimed: bool,
 }
 
 impl SerialTransport {
     pub fn new() -> Self {
         Self {
-            port: 0x3F8,  // COM1
+            port: crate::serial::CONSOLE_PORT,  // COM1
             baud_rate: 115200,
+            claimed: false,
+        }
+    }
+    
+    // Take the port from the console and /dev/ttyS*; console output on it
+    // stops until the transport is dropped
+    pub fn claim(&mut self) -> bool {
+        let Some(port) = crate::serial::port(self.port) else {
+            return false;
+        };
+        if port.claim(crate::serial::Owner::Debugger).is_err() {
+            return false;
         }
+        port.set_baud(self.baud_rate);
+        self.claimed = true;
+        true
     }
     
     pub fn send_break_sequence(&mut self) {
@@ -169,29 +185,13 @@ impl SerialTransport {
     }
     
     fn send_bytes(&mut self, bytes: &[u8]) {
-        for &byte in bytes {
-            self.send_byte(byte);
-        }
-    }
-    
-    fn send_byte(&mut self, byte: u8) {
-        unsafe {
-            // Wait for transmit buffer to be empty
-            while (x86_64::instructions::port::Port::<u8>::new(self.port + 5).read() & 0x20) == 0 {}
-            // Send byte
-            x86_64::instructions::port::Port::<u8>::new(self.port).write(byte);
+        if let Some(port) = crate::serial::port(self.port).filter(|_| self.claimed) {
+            port.write_polled(bytes);
         }
     }
     
     fn receive_byte(&mut self) -> Option<u8> {
-        unsafe {
-            // Check if data is available
-            if (x86_64::instructions::port::Port::<u8>::new(self.port + 5).read() & 0x01) != 0 {
-                Some(x86_64::instructions::port::Port::<u8>::new(self.port).read())
-            } else {
-                None
-            }
-        }
+        crate::serial::port(self.port).filter(|_| self.claimed)?.read_polled()
     }
     
     fn receive_u16(&mut self) -> Option<u16> {
@@ -213,6 +213,14 @@ impl SerialTransport {
     }
 }
 
+impl Drop for SerialTransport {
+    fn drop(&mut self) {
+        if let Some(port) = crate::serial::port(self.port).filter(|_| self.claimed) {
+            port.release(crate::serial::Owner::Debugger);
+        }
+    }
+}
+
 pub struct NetworkTransport {
     port: u16,
     connected: bool,
@@ -0,0 +1,174 @@
+//! Record storage
+//!
+//! Records are packed back to back in a fixed byte ring, so logging needs
+//! no heap and works from the first line of boot: a header, the source
+//! name, then the text without its newline. When a new record does not fit
+//! the oldest are dropped. Every record gets a sequence number, and readers
+//! keep their place by it; one whose records were dropped under it moves
+//! on to the oldest left.
+
+use super::Level;
+
+pub const LOG_SIZE: usize = 128 * 1024;
+
+// seq, timestamp, text length, console skip, level, cpu, source length, pad
+const HEADER_LEN: usize = 24;
+
+/// Longest text kept per record
+pub const MAX_TEXT: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub seq: u64,
+    pub timestamp_ns: u64,
+    pub text_len: u16,
+    /// Leading bytes of the text the console printed before the line was
+    /// finished
+    pub console_skip: u16,
+    pub level: Level,
+    pub cpu: u8,
+    pub source_len: u8,
+}
+
+impl Header {
+    fn len(&self) -> usize {
+        HEADER_LEN + self.source_len as usize + self.text_len as usize
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..8].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.timestamp_ns.to_le_bytes());
+        bytes[16..18].copy_from_slice(&self.text_len.to_le_bytes());
+        bytes[18..20].copy_from_slice(&self.console_skip.to_le_bytes());
+        bytes[20] = self.level as u8;
+        bytes[21] = self.cpu;
+        bytes[22] = self.source_len;
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HEADER_LEN]) -> Self {
+        Header {
+            seq: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            timestamp_ns: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            text_len: u16::from_le_bytes(bytes[16..18].try_into().unwrap()),
+            console_skip: u16::from_le_bytes(bytes[18..20].try_into().unwrap()),
+            level: Level::from_u8(bytes[20]),
+            cpu: bytes[21],
+            source_len: bytes[22],
+        }
+    }
+}
+
+/// Where a reader is: the sequence number of the next record and its
+/// offset in the ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub seq: u64,
+    pos: u64,
+}
+
+impl Cursor {
+    /// The first record of a buffer that has never dropped any
+    pub const START: Cursor = Cursor { seq: 0, pos: 0 };
+}
+
+pub struct LogBuffer {
+    data: [u8; LOG_SIZE],
+    // Free-running byte offsets of the oldest record and of the end
+    tail: u64,
+    head: u64,
+    first_seq: u64,
+    next_seq: u64,
+}
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        LogBuffer { data: [0; LOG_SIZE], tail: 0, head: 0, first_seq: 0, next_seq: 0 }
+    }
+
+    fn copy_in(&mut self, pos: u64, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.data[(pos as usize + i) % LOG_SIZE] = byte;
+        }
+    }
+
+    fn copy_out(&self, pos: u64, bytes: &mut [u8]) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.data[(pos as usize + i) % LOG_SIZE];
+        }
+    }
+
+    fn header_at(&self, pos: u64) -> Header {
+        let mut bytes = [0; HEADER_LEN];
+        self.copy_out(pos, &mut bytes);
+        Header::decode(&bytes)
+    }
+
+    /// Append a record, dropping the oldest to make room; returns its
+    /// sequence number
+    pub fn push(&mut self, mut header: Header, source: &str, text: &[u8]) -> u64 {
+        let source = &source.as_bytes()[..source.len().min(u8::MAX as usize)];
+        let text = &text[..text.len().min(MAX_TEXT)];
+        header.seq = self.next_seq;
+        header.source_len = source.len() as u8;
+        header.text_len = text.len() as u16;
+        header.console_skip = header.console_skip.min(header.text_len);
+
+        let len = header.len() as u64;
+        while self.head + len - self.tail > LOG_SIZE as u64 {
+            let oldest = self.header_at(self.tail);
+            self.tail += oldest.len() as u64;
+            self.first_seq += 1;
+        }
+        let pos = self.head;
+        self.copy_in(pos, &header.encode());
+        self.copy_in(pos + HEADER_LEN as u64, source);
+        self.copy_in(pos + HEADER_LEN as u64 + source.len() as u64, text);
+        self.head += len;
+        self.next_seq += 1;
+        header.seq
+    }
+
+    pub fn first(&self) -> Cursor {
+        Cursor { seq: self.first_seq, pos: self.tail }
+    }
+
+    pub fn end(&self) -> Cursor {
+        Cursor { seq: self.next_seq, pos: self.head }
+    }
+
+    /// Records between the two cursors
+    pub fn count(&self, from: Cursor, to: Cursor) -> u64 {
+        to.seq.saturating_sub(from.seq.max(self.first_seq))
+    }
+
+    /// Bring a cursor forward past dropped records, returning how many it
+    /// missed
+    pub fn catch_up(&self, cursor: &mut Cursor) -> u64 {
+        if cursor.seq >= self.first_seq {
+            return 0;
+        }
+        let missed = self.first_seq - cursor.seq;
+        *cursor = self.first();
+        missed
+    }
+
+    /// Read the record at `cursor` and step past it. `source` and `text`
+    /// receive as much as fits; their lengths are in the header.
+    pub fn read(&self, cursor: &mut Cursor, source: &mut [u8], text: &mut [u8]) -> Option<Header> {
+        self.catch_up(cursor);
+        if cursor.seq >= self.next_seq {
+            return None;
+        }
+        let header = self.header_at(cursor.pos);
+        let source_pos = cursor.pos + HEADER_LEN as u64;
+        let source_len = (header.source_len as usize).min(source.len());
+        self.copy_out(source_pos, &mut source[..source_len]);
+        let text_len = (header.text_len as usize).min(text.len());
+        self.copy_out(source_pos + header.source_len as u64, &mut text[..text_len]);
+        cursor.pos += header.len() as u64;
+        cursor.seq += 1;
+        Some(header)
+    }
+}
@@ -0,0 +1,727 @@
+//! Kernel log
+//!
+//! Messages go into one ring of records, each with its level, source
+//! module, CPU and time, rather than straight to the hardware. Printing
+//! holds the log lock only to copy text in; the consoles are written after,
+//! by whichever caller gets to them first, and never from an interrupt
+//! handler, whose messages wait for the main loop's next flush. A console
+//! shows only messages more urgent than its level, and a source that floods
+//! the log is cut down to a burst per interval once boot is over.
+//!
+//! A message without a newline stays open and later prints from the same
+//! CPU at the same level continue it; the consoles show its pieces as they
+//! come. Processes read the log through the `syslog` system call, with
+//! Linux's actions, and the shell through `dmesg`.
+
+pub mod buffer;
+pub mod ratelimit;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+use crate::boot::cmdline::Param;
+use crate::memory::userspace::validate_user_buffer;
+use crate::syscall::{EAGAIN, EFAULT, EINVAL, EPERM};
+use buffer::{Cursor, Header, LogBuffer, LOG_SIZE, MAX_TEXT};
+use ratelimit::{RateLimiter, Verdict};
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Level {
+    pub const ALL: [Level; 8] = [
+        Level::Emerg,
+        Level::Alert,
+        Level::Crit,
+        Level::Err,
+        Level::Warning,
+        Level::Notice,
+        Level::Info,
+        Level::Debug,
+    ];
+
+    /// Out-of-range values read as Debug
+    pub fn from_u8(value: u8) -> Self {
+        Self::ALL[(value as usize).min(Self::ALL.len() - 1)]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Emerg => "emerg",
+            Level::Alert => "alert",
+            Level::Crit => "crit",
+            Level::Err => "err",
+            Level::Warning => "warn",
+            Level::Notice => "notice",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// A level by name or number
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.parse::<usize>() {
+            Ok(value) => Self::ALL.get(value).copied(),
+            Err(_) => Self::ALL.iter().copied().find(|level| level.name() == s),
+        }
+    }
+}
+
+/// Console levels count as on Linux: a console prints messages whose level
+/// is below its own, so 8 prints everything and 1 only emergencies
+pub const CONSOLE_LEVEL_MIN: u8 = 1;
+pub const CONSOLE_LEVEL_MAX: u8 = 8;
+pub const CONSOLE_LEVEL_DEFAULT: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Serial,
+    /// Errors and worse only by default, as the shell shares the screen
+    Vga,
+}
+
+static SERIAL_LEVEL: AtomicU8 = AtomicU8::new(CONSOLE_LEVEL_DEFAULT);
+static VGA_LEVEL: AtomicU8 = AtomicU8::new(Level::Warning as u8);
+// The serial level from before SYSLOG_ACTION_CONSOLE_OFF, 0 if on
+static SAVED_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+impl Console {
+    pub const ALL: [Console; 2] = [Console::Serial, Console::Vga];
+
+    fn level_cell(self) -> &'static AtomicU8 {
+        match self {
+            Console::Serial => &SERIAL_LEVEL,
+            Console::Vga => &VGA_LEVEL,
+        }
+    }
+
+    pub fn level(self) -> u8 {
+        self.level_cell().load(Ordering::Relaxed)
+    }
+
+    /// Levels outside 1 to 8 are clamped
+    pub fn set_level(self, level: u8) {
+        self.level_cell().store(level.clamp(CONSOLE_LEVEL_MIN, CONSOLE_LEVEL_MAX), Ordering::Relaxed);
+    }
+
+    fn prints(self, level: Level) -> bool {
+        (level as u8) < self.level()
+    }
+
+    fn write(self, bytes: &[u8]) {
+        match self {
+            Console::Serial => crate::serial::console_write(bytes),
+            // Skipped rather than waited for while the screen is busy
+            Console::Vga => {
+                crate::vga_buffer::try_write_bytes(bytes);
+            }
+        }
+    }
+}
+
+static LOGLEVEL: Param<i64> = Param::new("loglevel", CONSOLE_LEVEL_DEFAULT as i64, "Serial console level, 1 to 8");
+static QUIET: Param<bool> = Param::new("quiet", false, "Print only errors and worse on the serial console");
+
+/// Set the serial console's level from the command line: `quiet` lowers it
+/// to errors and worse, and `loglevel=` sets it outright
+pub fn configure() {
+    if QUIET.get() {
+        Console::Serial.set_level(Level::Warning as u8);
+    }
+    if LOGLEVEL.is_set() {
+        let level = LOGLEVEL.get().clamp(CONSOLE_LEVEL_MIN as i64, CONSOLE_LEVEL_MAX as i64);
+        Console::Serial.set_level(level as u8);
+    }
+}
+
+fn printed_anywhere(level: Level) -> bool {
+    Console::ALL.iter().any(|console| console.prints(level))
+}
+
+// The line being built by prints without a newline
+struct ContLine {
+    active: bool,
+    cpu: u8,
+    level: Level,
+    source: &'static str,
+    timestamp_ns: u64,
+    len: usize,
+    // How much of it the consoles have been given
+    printed: usize,
+    text: [u8; MAX_TEXT],
+}
+
+struct Log {
+    buffer: LogBuffer,
+    cont: ContLine,
+    limiter: RateLimiter,
+    // The next record for the consoles, and for SYSLOG_ACTION_READ
+    console: Cursor,
+    reader: Cursor,
+    // Where SYSLOG_ACTION_CLEAR left the log for READ_ALL and dmesg
+    clear: Cursor,
+}
+
+// What the consoles print next
+enum Output {
+    /// Records dropped before the consoles got to them
+    Lost(u64),
+    /// `text[start..end]` at `level`, ending the line or not
+    Text { level: Level, start: usize, end: usize, newline: bool },
+}
+
+impl Log {
+    const fn new() -> Self {
+        Log {
+            buffer: LogBuffer::new(),
+            cont: ContLine {
+                active: false,
+                cpu: 0,
+                level: Level::Info,
+                source: "",
+                timestamp_ns: 0,
+                len: 0,
+                printed: 0,
+                text: [0; MAX_TEXT],
+            },
+            limiter: RateLimiter::new(),
+            console: Cursor::START,
+            reader: Cursor::START,
+            clear: Cursor::START,
+        }
+    }
+
+    // Whether a message may go in, and how many from its source were
+    // dropped before it. The rest of a line already started always may.
+    fn admit(&mut self, level: Level, source: &'static str, cpu: u8, now: u64) -> Option<u32> {
+        let continuing = self.cont.active && self.cont.cpu == cpu;
+        if level <= Level::Crit
+            || continuing
+            || !RATELIMIT_ON.load(Ordering::Relaxed)
+            || PANICKING.load(Ordering::Relaxed)
+        {
+            return Some(0);
+        }
+        match self.limiter.check(source, now) {
+            Verdict::Allow { suppressed } => Some(suppressed),
+            Verdict::Drop => None,
+        }
+    }
+
+    fn start(&mut self, level: Level, source: &'static str, cpu: u8, now: u64) {
+        let cont = &mut self.cont;
+        cont.active = true;
+        cont.cpu = cpu;
+        cont.level = level;
+        cont.source = source;
+        cont.timestamp_ns = now;
+        cont.len = 0;
+        cont.printed = 0;
+    }
+
+    fn commit(&mut self) {
+        let cont = &mut self.cont;
+        if !cont.active {
+            return;
+        }
+        let header = Header {
+            seq: 0,
+            timestamp_ns: cont.timestamp_ns,
+            text_len: 0,
+            console_skip: cont.printed as u16,
+            level: cont.level,
+            cpu: cont.cpu,
+            source_len: 0,
+        };
+        self.buffer.push(header, cont.source, &cont.text[..cont.len]);
+        cont.active = false;
+    }
+
+    fn append(&mut self, level: Level, source: &'static str, cpu: u8, now: u64, bytes: &[u8]) {
+        if self.cont.active && (self.cont.cpu != cpu || self.cont.level != level) {
+            self.commit();
+        }
+        for &byte in bytes {
+            if !self.cont.active {
+                self.start(level, source, cpu, now);
+            }
+            if byte == b'\n' {
+                self.commit();
+                continue;
+            }
+            if self.cont.len == MAX_TEXT {
+                self.commit();
+                self.start(level, source, cpu, now);
+            }
+            self.cont.text[self.cont.len] = byte;
+            self.cont.len += 1;
+        }
+    }
+
+    fn console_pending(&self) -> bool {
+        self.buffer.count(self.console, self.buffer.end()) > 0 || (self.cont.active && self.cont.printed < self.cont.len)
+    }
+
+    fn next_output(&mut self, text: &mut [u8; MAX_TEXT]) -> Option<Output> {
+        let missed = self.buffer.catch_up(&mut self.console);
+        if missed > 0 {
+            return Some(Output::Lost(missed));
+        }
+        while let Some(header) = self.buffer.read(&mut self.console, &mut [], text) {
+            if printed_anywhere(header.level) {
+                let (start, end) = (header.console_skip as usize, header.text_len as usize);
+                return Some(Output::Text { level: header.level, start, end, newline: true });
+            }
+        }
+        let cont = &mut self.cont;
+        if !cont.active || cont.printed == cont.len {
+            return None;
+        }
+        let (start, end) = (cont.printed, cont.len);
+        cont.printed = end;
+        if !printed_anywhere(cont.level) {
+            return None;
+        }
+        text[start..end].copy_from_slice(&cont.text[start..end]);
+        Some(Output::Text { level: cont.level, start, end, newline: false })
+    }
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log::new());
+// Held by whoever is writing the log out to the consoles
+static CONSOLE: Mutex<()> = Mutex::new(());
+static RATELIMIT_ON: AtomicBool = AtomicBool::new(false);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// Formatted output goes into the log this much at a time
+const CHUNK: usize = 128;
+
+// Copies formatted text into the log a chunk at a time, so the log lock is
+// never held while arguments are formatted
+struct LogWriter {
+    level: Level,
+    source: &'static str,
+    cpu: u8,
+    now: u64,
+    len: usize,
+    chunk: [u8; CHUNK],
+}
+
+impl LogWriter {
+    fn flush_chunk(&mut self) {
+        if self.len > 0 {
+            let bytes = &self.chunk[..self.len];
+            interrupts::without_interrupts(|| {
+                LOG.lock().append(self.level, self.source, self.cpu, self.now, bytes)
+            });
+            self.len = 0;
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == CHUNK {
+                self.flush_chunk();
+            }
+            self.chunk[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// Straight to the consoles, for notices about the log itself
+struct ConsoleWriter(Level);
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        emit(self.0, s.as_bytes(), false);
+        Ok(())
+    }
+}
+
+// The boot CPU runs without a per-CPU area, leaving GS at zero
+fn cpu_id() -> u8 {
+    if GsBase::read().as_u64() == 0 {
+        0
+    } else {
+        crate::smp::current_cpu_id() as u8
+    }
+}
+
+/// Start limiting sources to a burst per interval; boot logs too much at
+/// once to do so from the start
+pub fn init() {
+    RATELIMIT_ON.store(true, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _print(level: Level, source: &'static str, args: fmt::Arguments) {
+    let now = crate::time::monotonic_ns();
+    let cpu = cpu_id();
+    let Some(suppressed) = interrupts::without_interrupts(|| LOG.lock().admit(level, source, cpu, now)) else {
+        return;
+    };
+    if suppressed > 0 {
+        let mut notice = LogWriter { level: Level::Warning, source, cpu, now, len: 0, chunk: [0; CHUNK] };
+        let _ = writeln!(notice, "klog: {} messages from {} suppressed", suppressed, source);
+        notice.flush_chunk();
+    }
+    let mut writer = LogWriter { level, source, cpu, now, len: 0, chunk: [0; CHUNK] };
+    let _ = writer.write_fmt(args);
+    writer.flush_chunk();
+    flush();
+}
+
+fn emit(level: Level, bytes: &[u8], newline: bool) {
+    for console in Console::ALL.iter().filter(|console| console.prints(level)) {
+        console.write(bytes);
+        if newline {
+            console.write(b"\n");
+        }
+    }
+}
+
+/// Write out what the consoles have not shown yet. Does nothing in an
+/// interrupt handler, or while another caller is at it, as that one picks
+/// up the new records before it stops.
+pub fn flush() {
+    if crate::sync::lockdep::in_hardirq() && !PANICKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut text = [0; MAX_TEXT];
+    loop {
+        {
+            let Some(_console) = CONSOLE.try_lock() else {
+                return;
+            };
+            while let Some(output) = interrupts::without_interrupts(|| LOG.lock().next_output(&mut text)) {
+                match output {
+                    Output::Lost(count) => {
+                        let _ = writeln!(ConsoleWriter(Level::Warning), "klog: {} messages lost", count);
+                    }
+                    Output::Text { level, start, end, newline } => emit(level, &text[start..end], newline),
+                }
+            }
+        }
+        // Records added between the last look and unlocking would otherwise
+        // wait for the next print
+        if !interrupts::without_interrupts(|| LOG.lock().console_pending()) {
+            return;
+        }
+    }
+}
+
+unsafe fn break_locks() {
+    if LOG.is_locked() {
+        LOG.force_unlock();
+    }
+    if CONSOLE.is_locked() {
+        CONSOLE.force_unlock();
+    }
+}
+
+/// Make the log usable from an NMI handler. A CPU interrupted in the middle
+/// of printing never releases the locks, so once other CPUs have had time
+/// to finish they are broken; output may then interleave.
+pub fn unlock_for_nmi() {
+    for _ in 0..10_000_000 {
+        if !LOG.is_locked() && !CONSOLE.is_locked() {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { break_locks() };
+    crate::serial::unlock_for_nmi();
+}
+
+/// Print everything from here on synchronously, even from interrupt
+/// handlers, and write out what is pending. For the panic handler, which
+/// may have interrupted a print on this CPU, so the locks are broken.
+pub fn panic_mode() {
+    PANICKING.store(true, Ordering::Relaxed);
+    unsafe { break_locks() };
+    crate::serial::unlock_for_nmi();
+    flush();
+}
+
+// The record at `cursor`, stepping past it, with the lock held only for the
+// copy
+fn next_record(cursor: &mut Cursor, text: &mut [u8; MAX_TEXT]) -> Option<Header> {
+    interrupts::without_interrupts(|| LOG.lock().buffer.read(cursor, &mut [], text))
+}
+
+/// `<level>[seconds.micros] text`, the prefix only with `with_level`
+fn format_line(line: &mut Vec<u8>, header: &Header, text: &[u8], with_level: bool) {
+    let mut prefix = String::new();
+    if with_level {
+        let _ = write!(prefix, "<{}>", header.level as u8);
+    }
+    let seconds = header.timestamp_ns / 1_000_000_000;
+    let micros = header.timestamp_ns % 1_000_000_000 / 1_000;
+    let _ = write!(prefix, "[{:5}.{:06}] ", seconds, micros);
+    line.extend_from_slice(prefix.as_bytes());
+    line.extend_from_slice(&text[..(header.text_len as usize).min(text.len())]);
+    line.push(b'\n');
+}
+
+/// Every line since the log was last cleared at least as urgent as
+/// `max_level`, oldest first
+pub fn lines(max_level: Level, with_level: bool) -> Vec<Vec<u8>> {
+    let mut cursor = interrupts::without_interrupts(|| LOG.lock().clear);
+    let mut text = [0; MAX_TEXT];
+    let mut lines = Vec::new();
+    while let Some(header) = next_record(&mut cursor, &mut text) {
+        if header.level <= max_level {
+            let mut line = Vec::new();
+            format_line(&mut line, &header, &text, with_level);
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Forget what is in the log as far as `lines` and SYSLOG_ACTION_READ_ALL
+/// are concerned
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        let mut log = LOG.lock();
+        log.clear = log.buffer.end();
+    });
+}
+
+// Crash dumps keep this much of the newest log
+const SNAPSHOT_SIZE: usize = 16 * 1024;
+
+/// The newest lines of the log, for crash dumps. Safe to call from the
+/// panic path: a log left locked by the CPU that panicked is read anyway.
+pub fn snapshot() -> Vec<u8> {
+    if LOG.try_lock().is_none() {
+        unsafe { LOG.force_unlock() };
+    }
+    let mut cursor = interrupts::without_interrupts(|| LOG.lock().buffer.first());
+    let mut text = [0; MAX_TEXT];
+    let mut snapshot = Vec::new();
+    while let Some(header) = next_record(&mut cursor, &mut text) {
+        format_line(&mut snapshot, &header, &text, false);
+    }
+    let excess = snapshot.len().saturating_sub(SNAPSHOT_SIZE);
+    if excess > 0 {
+        // Start on a whole line
+        let start = snapshot[excess..].iter().position(|&byte| byte == b'\n').map_or(excess, |i| excess + i + 1);
+        snapshot.drain(..start);
+    }
+    snapshot
+}
+
+pub const SYSLOG_ACTION_CLOSE: usize = 0;
+pub const SYSLOG_ACTION_OPEN: usize = 1;
+pub const SYSLOG_ACTION_READ: usize = 2;
+pub const SYSLOG_ACTION_READ_ALL: usize = 3;
+pub const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+pub const SYSLOG_ACTION_CLEAR: usize = 5;
+pub const SYSLOG_ACTION_CONSOLE_OFF: usize = 6;
+pub const SYSLOG_ACTION_CONSOLE_ON: usize = 7;
+pub const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+pub const SYSLOG_ACTION_SIZE_UNREAD: usize = 9;
+pub const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+fn copy_to_user(buf: usize, bytes: &[u8]) -> Result<usize, usize> {
+    let addr = VirtAddr::try_new(buf as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, bytes.len()) {
+        return Err(EFAULT);
+    }
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len()) };
+    Ok(bytes.len())
+}
+
+// Unread lines that fit in `len` bytes, a lone line that does not being
+// cut short, and the cursor past them
+fn read_unread(len: usize) -> (Vec<u8>, Cursor) {
+    let mut cursor = interrupts::without_interrupts(|| LOG.lock().reader);
+    let mut text = [0; MAX_TEXT];
+    let mut out = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        let mut next = cursor;
+        let Some(header) = next_record(&mut next, &mut text) else {
+            break;
+        };
+        line.clear();
+        format_line(&mut line, &header, &text, true);
+        if out.len() + line.len() > len {
+            if out.is_empty() {
+                out.extend_from_slice(&line[..len]);
+                cursor = next;
+            }
+            break;
+        }
+        out.extend_from_slice(&line);
+        cursor = next;
+    }
+    (out, cursor)
+}
+
+/// The `syslog` system call. Clearing the log and changing the console are
+/// for administrators.
+pub fn syslog(action: usize, buf: usize, len: usize) -> Result<usize, usize> {
+    let privileged = matches!(
+        action,
+        SYSLOG_ACTION_READ_CLEAR
+            | SYSLOG_ACTION_CLEAR
+            | SYSLOG_ACTION_CONSOLE_OFF
+            | SYSLOG_ACTION_CONSOLE_ON
+            | SYSLOG_ACTION_CONSOLE_LEVEL
+    );
+    if privileged && !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    match action {
+        SYSLOG_ACTION_CLOSE | SYSLOG_ACTION_OPEN => Ok(0),
+        // Reads what no earlier READ returned; nothing to wait on yet, so
+        // an empty log fails rather than blocks
+        SYSLOG_ACTION_READ => {
+            let (bytes, cursor) = read_unread(len);
+            if bytes.is_empty() && len > 0 {
+                return Err(EAGAIN);
+            }
+            let count = copy_to_user(buf, &bytes)?;
+            interrupts::without_interrupts(|| {
+                let mut log = LOG.lock();
+                if cursor.seq > log.reader.seq {
+                    log.reader = cursor;
+                }
+            });
+            Ok(count)
+        }
+        // The newest lines that fit
+        SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+            let lines = lines(Level::Debug, true);
+            let mut total = 0;
+            let newest = lines.iter().rev().take_while(|line| {
+                total += line.len();
+                total <= len
+            });
+            let first = lines.len() - newest.count();
+            let bytes: Vec<u8> = lines[first..].concat();
+            let count = copy_to_user(buf, &bytes)?;
+            if action == SYSLOG_ACTION_READ_CLEAR {
+                clear();
+            }
+            Ok(count)
+        }
+        SYSLOG_ACTION_CLEAR => {
+            clear();
+            Ok(0)
+        }
+        SYSLOG_ACTION_CONSOLE_OFF => {
+            let level = Console::Serial.level();
+            if SAVED_LEVEL.compare_exchange(0, level, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                Console::Serial.set_level(CONSOLE_LEVEL_MIN);
+            }
+            Ok(0)
+        }
+        SYSLOG_ACTION_CONSOLE_ON => {
+            let saved = SAVED_LEVEL.swap(0, Ordering::Relaxed);
+            if saved != 0 {
+                Console::Serial.set_level(saved);
+            }
+            Ok(0)
+        }
+        SYSLOG_ACTION_CONSOLE_LEVEL => {
+            if !(CONSOLE_LEVEL_MIN as usize..=CONSOLE_LEVEL_MAX as usize).contains(&len) {
+                return Err(EINVAL);
+            }
+            Console::Serial.set_level(len as u8);
+            SAVED_LEVEL.store(0, Ordering::Relaxed);
+            Ok(0)
+        }
+        SYSLOG_ACTION_SIZE_UNREAD => Ok(read_unread(usize::MAX).0.len()),
+        SYSLOG_ACTION_SIZE_BUFFER => Ok(LOG_SIZE),
+        _ => Err(EINVAL),
+    }
+}
+
+/// A message at a given level, e.g. `klog!(Level::Warning, "...")`, logged
+/// as a line of its own
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::klog::_print($level, module_path!(), format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! pr_emerg {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Emerg, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_alert {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Alert, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_crit {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Crit, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_err {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Err, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_warn {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Warning, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_notice {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Notice, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_info {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! pr_debug {
+    ($($arg:tt)*) => ($crate::klog!($crate::klog::Level::Debug, $($arg)*));
+}
+
+/// Info-level output, a line at a time or in pieces
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::klog::_print($crate::klog::Level::Info, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
@@ -0,0 +1,81 @@
+//! Per-source rate limiting
+//!
+//! Each source, the module a message comes from, may log a burst of
+//! messages per interval; the rest of the interval's messages are dropped
+//! and counted, and the count is reported when the source next gets a
+//! message through. Sources past the table's size share its last slot.
+
+pub const DEFAULT_INTERVAL_NS: u64 = 5_000_000_000;
+pub const DEFAULT_BURST: u32 = 100;
+
+const MAX_SOURCES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    source: &'static str,
+    window_start: u64,
+    count: u32,
+    suppressed: u32,
+}
+
+pub struct RateLimiter {
+    buckets: [Bucket; MAX_SOURCES],
+    used: usize,
+    pub interval_ns: u64,
+    /// 0 turns limiting off
+    pub burst: u32,
+}
+
+/// Whether a message may go through, and how many from its source were
+/// dropped since the last one that did
+pub enum Verdict {
+    Allow { suppressed: u32 },
+    Drop,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        RateLimiter {
+            buckets: [Bucket { source: "", window_start: 0, count: 0, suppressed: 0 }; MAX_SOURCES],
+            used: 0,
+            interval_ns: DEFAULT_INTERVAL_NS,
+            burst: DEFAULT_BURST,
+        }
+    }
+
+    fn bucket(&mut self, source: &'static str) -> &mut Bucket {
+        let index = match self.buckets[..self.used].iter().position(|bucket| bucket.source == source) {
+            Some(index) => index,
+            None if self.used < MAX_SOURCES => {
+                self.buckets[self.used].source = source;
+                self.used += 1;
+                self.used - 1
+            }
+            None => MAX_SOURCES - 1,
+        };
+        &mut self.buckets[index]
+    }
+
+    pub fn check(&mut self, source: &'static str, now_ns: u64) -> Verdict {
+        if self.burst == 0 {
+            return Verdict::Allow { suppressed: 0 };
+        }
+        let (interval, burst) = (self.interval_ns, self.burst);
+        let bucket = self.bucket(source);
+        if now_ns.saturating_sub(bucket.window_start) >= interval {
+            bucket.window_start = now_ns;
+            bucket.count = 0;
+        }
+        if bucket.count >= burst {
+            bucket.suppressed = bucket.suppressed.saturating_add(1);
+            return Verdict::Drop;
+        }
+        bucket.count += 1;
+        Verdict::Allow { suppressed: core::mem::take(&mut bucket.suppressed) }
+    }
+
+    /// Messages dropped so far and not yet reported, over all sources
+    pub fn pending_suppressed(&self) -> u64 {
+        self.buckets[..self.used].iter().map(|bucket| bucket.suppressed as u64).sum()
+    }
+}
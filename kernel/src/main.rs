@@ -8,8 +8,10 @@
 use core::panic::PanicInfo;
 // use alloc::string::ToString;
 
+mod boot;
 mod vga_buffer;
 mod serial;
+mod klog;
 mod interrupts;
 mod gdt;
 mod memory;
@@ -22,6 +24,7 @@ mod win32;
 mod process;
 mod kd;
 mod drivers;
+mod driver;
 mod shell;
 mod cmd_shell;
 mod fs;
@@ -33,7 +36,12 @@ mod usb;
 mod ahci;
 mod sound;
 mod nvme;
+mod sdhci;
+mod i2c;
+mod input;
 mod pcie;
+mod virtio;
+mod io_uring;
 mod syscall;
 mod timer;
 mod security;
@@ -42,6 +50,9 @@ mod perf;
 mod numa;
 mod printing;
 mod scanning;
+mod clipboard;
+mod vnc;
+mod rdp;
 mod task;
 mod time;
 mod multimedia;
@@ -50,6 +61,8 @@ mod bluetooth;
 mod power;
 mod thermal;
 mod hypervisor;
+mod virt;
+mod paravirt;
 mod container;
 mod debug;  // Advanced debugging infrastructure
 mod monitoring;
@@ -67,8 +80,15 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// Entry from the bootimage loader; the Multiboot2 and Limine entry points
+/// are in boot/
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(boot_info: &'static bootloader::BootInfo) -> ! {
+    boot::set(boot::bootimage::parse(boot_info));
+    kernel_main()
+}
+
+pub fn kernel_main() -> ! {
     println!("Rust OS Starting...");
     serial_println!("Stage 1: Starting kernel");
     
@@ -94,6 +114,10 @@ pub extern "C" fn _start() -> ! {
     serial_println!("Stage 5: About to init heap allocator");
     allocator::init_heap();
     serial_println!("Stage 5b: Heap initialized");
+    boot::print_summary();
+    klog::configure();
+    memory::init(boot::info());
+    sync::lockdep::init();
     
     // Detect CPU features
     println!("Detecting CPU features...");
@@ -107,11 +131,26 @@ pub extern "C" fn _start() -> ! {
     serial_println!("Stage 5d1: Initializing SMP");
     smp::init_bsp();
     serial_println!("Stage 5d2: BSP initialized");
+
+    // Switch to hypervisor clocks and timers when running as a guest
+    println!("Detecting hypervisor...");
+    serial_println!("Stage 5d3: Initializing paravirt support");
+    paravirt::init();
+
+    // Clocksource and high-resolution timers, after paravirt so a
+    // hypervisor clock can be picked
+    println!("Initializing clocksource and high-resolution timers...");
+    serial_println!("Stage 5d4: Initializing HPET and TSC");
+    time::init();
+
+    // Idle states, and whether the HPET can wake the boot CPU from them
+    serial_println!("Stage 5d5: Initializing CPU idle");
+    power::idle::init();
     
     // Initialize security subsystem
     println!("Initializing security features...");
     serial_println!("Stage 5e: Initializing security");
-    let security_config = security::SecurityConfig::default();
+    let security_config = security::SecurityConfig::from_cmdline();
     security::init(security_config);
     serial_println!("Stage 5f: Security initialized");
     
@@ -129,8 +168,17 @@ pub extern "C" fn _start() -> ! {
     // Initialize NUMA subsystem
     println!("Initializing NUMA subsystem...");
     serial_println!("Stage 5h: Initializing NUMA");
+    fs::procfs::init();
     numa::init();
     
+    // Reclaim into compressed swap and same-page merging, both opt-in, and
+    // the page cache
+    serial_println!("Stage 5h1: Initializing demand paging");
+    memory::demand_paging::init_demand_paging();
+    memory::zram::init();
+    memory::ksm::init();
+    memory::page_cache::init();
+    
     // Initialize advanced power management
     println!("Initializing advanced power management...");
     serial_println!("Stage 5i: Initializing power management");
@@ -149,6 +197,11 @@ pub extern "C" fn _start() -> ! {
         serial_println!("Stage 5j: Thermal management initialized successfully");
     }
     
+    // Initialize hardware virtualization host (/dev/kvm)
+    println!("Initializing hardware virtualization...");
+    serial_println!("Stage 5ja: Probing VT-x/AMD-V");
+    virt::init();
+    
     // Initialize fast syscall mechanism
     println!("Initializing fast syscall (SYSCALL/SYSRET)...");
     serial_println!("Stage 5k: Initializing fast syscall");
@@ -182,8 +235,10 @@ pub extern "C" fn _start() -> ! {
     interrupts::set_keyboard_handler(handle_keyboard_input);
     serial_println!("Stage 6b: Keyboard handler set");
     
-    // Skip serial interrupt - will use polling instead
-    serial_println!("Stage 6c: Serial input will use polling");
+    // Serial ports queue input from their interrupts, or from polling
+    // while interrupts are off
+    serial_println!("Stage 6c: Initializing serial ports");
+    serial::init();
     
     println!("Enabling interrupts...");
     serial_println!("Stage 6d: About to enable interrupts");
@@ -194,9 +249,9 @@ pub extern "C" fn _start() -> ! {
     // Clear any pending interrupts and unmask the ones we need
     unsafe {
         let mut pics = interrupts::PICS.lock();
-        // Enable only timer (IRQ0) and keyboard (IRQ1)
-        // 0xFC = 11111100 (enable IRQ0,1), 0xFF = all masked on PIC2
-        pics.write_masks(0xFC, 0xFF);
+        // Enable only timer (IRQ0), keyboard (IRQ1) and serial (IRQ3, IRQ4)
+        // 0xE4 = 11100100 (enable IRQ0,1,3,4), 0xFF = all masked on PIC2
+        pics.write_masks(0xE4, 0xFF);
     }
     
     // Skip enabling interrupts for now - there's a deadlock issue we need to fix
@@ -231,6 +286,19 @@ pub extern "C" fn _start() -> ! {
     }
     serial_println!("Stage 11a: Process executor initialized");
     
+    // An initrd becomes the root and runs its init before disks are probed
+    serial_println!("Stage 11b: Checking for an initrd");
+    if let Some(mount_point) = init_initrd() {
+        serial_println!("Stage 11c: initrd mounted on {}", mount_point);
+    }
+    
+    // Bus enumeration; drivers bind to what they match as it is found
+    serial_println!("Stage 11d: Enumerating devices");
+    driver::init();
+    pcie::hotplug::init();
+    pcie::aer::init();
+    serial_println!("Stage 11e: Device model ready");
+    
     // Initialize disk drivers
     serial_println!("Stage 12: Initializing disk drivers");
     {
@@ -238,12 +306,17 @@ pub extern "C" fn _start() -> ! {
         let mut disk_manager = drivers::disk::DISK_MANAGER.lock();
         disk_manager.init();
     }
+    drivers::block::init();
     serial_println!("Stage 12a: Disk drivers initialized");
+    power::device::init();
     
     // Initialize file system with proper mutex handling
     serial_println!("Stage 13: Initializing file system with improved mutex handling");
     init_filesystem();
     serial_println!("Stage 13a: File system initialized successfully");
+    security::audit_log::init();
+    memory::swap::init();
+    fs::ninep::init();
     
     // Initialize printing subsystem
     serial_println!("Stage 13b: Initializing printing subsystem");
@@ -261,6 +334,22 @@ pub extern "C" fn _start() -> ! {
         serial_println!("Stage 13e: Scanning subsystem initialized successfully");
     }
     
+    // Remote display, if the command line asks for it
+    serial_println!("Stage 13f: Checking for a VNC server");
+    vnc::init();
+    serial_println!("Stage 13g: Checking for an RDP server");
+    rdp::init();
+    serial_println!("Stage 13h: Checking for an HTTP server");
+    net::http::init();
+    
+    driver::late_init();
+    
+    // Every subsystem that reads an option has done so by now
+    let unrecognized = boot::cmdline::unrecognized();
+    if !unrecognized.is_empty() {
+        serial_println!("Unknown kernel command line parameters: {}", unrecognized.join(" "));
+    }
+    
     serial_println!("Stage 14: System ready for shell");
     
     #[cfg(test)]
@@ -318,23 +407,51 @@ fn handle_keyboard_input(character: char) {
     cmd_shell::handle_keyboard_input(character);
 }
 
+// Mount the bootloader's initrd and start its init program. It is the root
+// unless `root=` names a disk, which then takes its place.
+fn init_initrd() -> Option<&'static str> {
+    use fs::vfs::VFS;
+    use alloc::boxed::Box;
+    
+    let root = fs::ROOT.get();
+    let Some(ramfs) = fs::initrd::load() else {
+        if root == fs::Root::Initrd {
+            serial_println!("root=initrd given, but the loader passed no usable initrd");
+        }
+        return None;
+    };
+    let mount_point = if matches!(root, fs::Root::Disk(_)) { "/initrd" } else { "/" };
+    VFS.lock().mount(alloc::string::String::from(mount_point), Box::new(ramfs));
+    
+    if let Err(e) = fs::initrd::run_init(mount_point) {
+        serial_println!("initrd: {} not started: {}", fs::initrd::init_path(), e);
+    }
+    Some(mount_point)
+}
+
 // Initialize file system with proper error handling
 fn init_filesystem() {
     use fs::vfs::VFS;
     use alloc::boxed::Box;
     
-    serial_println!("Attempting to mount FAT32 filesystem...");
+    let disk = fs::chkdsk::root_disk();
+    // With an initrd as the root, the disk is mounted beside it for early
+    // userspace to switch to
+    let mount_point = if VFS.lock().is_mounted("/") { "/sysroot" } else { "/" };
+    
+    fs::chkdsk::boot_check(disk);
+    serial_println!("Attempting to mount FAT32 filesystem from disk {}...", disk);
     
     // Create filesystem outside of VFS lock to avoid nested locking
-    let fat32_result = fs::fat32::Fat32FileSystem::new(0);
+    let fat32_result = fs::fat32::Fat32FileSystem::new(disk);
     
     match fat32_result {
         Ok(fat32_fs) => {
-            serial_println!("FAT32 filesystem found, mounting on /");
+            serial_println!("FAT32 filesystem found, mounting on {}", mount_point);
             // Only lock VFS when actually mounting
             {
                 let mut vfs = VFS.lock();
-                vfs.mount(alloc::string::String::from("/"), Box::new(fat32_fs));
+                vfs.mount(alloc::string::String::from(mount_point), Box::new(fat32_fs));
             }
             serial_println!("FAT32 filesystem mounted successfully");
         }
@@ -349,79 +466,59 @@ pub fn hlt_loop() -> ! {
     loop {
         // With interrupts enabled, we can use hlt to save power
         x86_64::instructions::hlt();
+        debug::watchdog::touch();
     }
 }
 
+// How often the keyboard and serial port are polled; a 16550's FIFO holds
+// about 1.4 ms of input at 115200 baud
+const POLL_INTERVAL_NS: u64 = 1_000_000;
+
 pub fn main_loop() -> ! {
     use x86_64::instructions::port::Port;
     
     serial_println!("Entering polling loop for keyboard/serial input");
+    klog::init();
+    input::ps2::attach();
     
     loop {
-        // Poll for keyboard input (since interrupts are disabled)
+        // Idle and polling with interrupts off; tell the lockup detectors
+        debug::watchdog::touch();
+
+        // Poll for keyboard input (since interrupts are disabled). Keys go
+        // to the input layer, which types them at the shell.
         unsafe {
             let mut status_port = Port::<u8>::new(0x64);
             let mut data_port = Port::<u8>::new(0x60);
             
             // Check if there's data available
             if status_port.read() & 0x01 != 0 {
-                let scancode = data_port.read();
-                
-                // Only process key-down events (scancode < 0x80)
-                if scancode < 0x80 {
-                    // Simple scancode to ASCII conversion (US layout)
-                    let character = match scancode {
-                        0x1C => '\n',  // Enter
-                        0x0E => '\x08', // Backspace
-                        0x39 => ' ',   // Space
-                        0x02 => '1',
-                        0x03 => '2',
-                        0x04 => '3',
-                        0x05 => '4',
-                        0x06 => '5',
-                        0x07 => '6',
-                        0x08 => '7',
-                        0x09 => '8',
-                        0x0A => '9',
-                        0x0B => '0',
-                        0x10 => 'q',
-                        0x11 => 'w',
-                        0x12 => 'e',
-                        0x13 => 'r',
-                        0x14 => 't',
-                        0x15 => 'y',
-                        0x16 => 'u',
-                        0x17 => 'i',
-                        0x18 => 'o',
-                        0x19 => 'p',
-                        0x1E => 'a',
-                        0x1F => 's',
-                        0x20 => 'd',
-                        0x21 => 'f',
-                        0x22 => 'g',
-                        0x23 => 'h',
-                        0x24 => 'j',
-                        0x25 => 'k',
-                        0x26 => 'l',
-                        0x2C => 'z',
-                        0x2D => 'x',
-                        0x2E => 'c',
-                        0x2F => 'v',
-                        0x30 => 'b',
-                        0x31 => 'n',
-                        0x32 => 'm',
-                        0x34 => '.',
-                        0x35 => '/',
-                        _ => continue, // Skip unknown scancodes
-                    };
-                    
-                    // Pass to shell
-                    cmd_shell::handle_keyboard_input(character);
-                }
+                input::ps2::scancode(data_port.read());
             }
         }
         
-        // Poll for serial input as backup
+        // Log messages from interrupt handlers, which leave them for here
+        klog::flush();
+        // Security events for writes to watched kernel memory and for
+        // processes CET ended
+        security::watchpoints::poll();
+        security::mitigations::poll();
+        
+        // Serial input, and output queued for /dev/ttyS*, and the same for
+        // USB serial adapters
+        serial::poll();
+        usb::serial::poll();
+        // USB transfers queued on their endpoints, and their completions
+        usb::urb::poll();
+        // HCI packets the Bluetooth controllers have sent, and those waiting
+        // for them
+        bluetooth::poll();
+        // Scans, associations and WPA2 handshakes on the Wi-Fi adapters
+        net::wireless::poll();
+        // Frames the network interfaces have received, and the DHCP
+        // replies among them
+        net::interface::poll();
+        net::dhcp::poll();
         if let Some(byte) = serial::read_byte() {
             // Handle special characters
             let character = match byte {
@@ -435,10 +532,54 @@ pub fn main_loop() -> ! {
             cmd_shell::handle_keyboard_input(character);
         }
         
-        // Small delay to prevent CPU spinning
-        for _ in 0..10000 {
-            core::hint::spin_loop();
-        }
+        // Programs the shell started that have since ended
+        cmd_shell::poll();
+        
+        // Interrupts are off, so expired high-resolution timers and the TCP
+        // timers they flag are run from here
+        time::hrtimer::run_expired();
+        net::tcp::poll_timers();
+        
+        // Hot-plug slots and PCIe error status
+        pcie::poll();
+        
+        // SD cards put in and taken out
+        sdhci::poll();
+        
+        // Touchpads, key repeat and what the keyboards typed, and laptop
+        // hotkeys
+        input::poll();
+        acpi::ec::poll();
+        
+        // The pointer's packets for user windows, and the desktop composed
+        // when they have drawn
+        graphics::client::poll();
+        
+        // Print jobs: a page rendered or more output sent each time
+        printing::poll();
+        
+        // Scan jobs: a strip read or a response taken in each time
+        scanning::poll();
+        
+        // Remote display: new viewers, their input, and screen updates
+        vnc::poll();
+        rdp::poll();
+        
+        // HTTP fetches under way, and requests to the status server
+        net::http::poll();
+        
+        // Same-page merging, reclaim when memory runs low, and write-back
+        memory::poll();
+        
+        // Devices and disks idle long enough to be powered down, and
+        // devices asking to be woken
+        power::device::poll();
+
+        // A shutdown or reboot a system call asked for
+        power::shutdown::poll();
+        
+        // Sleep until the next timer or poll, whichever comes first
+        power::idle::enter(POLL_INTERVAL_NS);
     }
 }
 
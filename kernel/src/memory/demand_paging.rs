@@ -2,15 +2,20 @@
 use x86_64::{
     structures::paging::{
         Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
-        mapper::{Mapper, MapperAllSizes, MappedFrame},
+        mapper::{Mapper, MapperAllSizes, MappedFrame, Translate, TranslateResult},
         frame::PhysFrameRange,
+        OffsetPageTable,
     },
     VirtAddr, PhysAddr,
 };
 use spin::Mutex;
 use alloc::vec::Vec;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::PHYS_MEM_OFFSET;
+use super::page_cache::{self, FileKey};
 
 // Page fault error codes
 pub const PAGE_FAULT_PRESENT: u64 = 1 << 0;
@@ -18,6 +23,18 @@ pub const PAGE_FAULT_WRITE: u64 = 1 << 1;
 pub const PAGE_FAULT_USER: u64 = 1 << 2;
 pub const PAGE_FAULT_RESERVED_WRITE: u64 = 1 << 3;
 pub const PAGE_FAULT_INSTRUCTION_FETCH: u64 = 1 << 4;
+pub const PAGE_FAULT_SHADOW_STACK: u64 = 1 << 6;
+
+/// The flags of a user shadow stack page: with CET, dirty and read-only
+/// marks a page only calls, returns and the kernel's `wrussq` may write
+pub const SHADOW_STACK_FLAGS: PageTableFlags = PageTableFlags::USER_ACCESSIBLE
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PageTableFlags::DIRTY);
+
+/// Whether pages with `flags` are a shadow stack
+pub fn is_shadow_stack(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::DIRTY) && !flags.contains(PageTableFlags::WRITABLE)
+}
 
 // Page states
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -27,6 +44,17 @@ pub enum PageState {
     InMemory,            // Page in physical memory
     CopyOnWrite,         // COW page, shared until write
     Zero,                // Zero page, allocated on first access
+    Compressed,          // Page kept compressed in zram
+    File,                // File page, read from the page cache on access
+}
+
+// Where a file-backed page comes from
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilePage {
+    pub key: FileKey,
+    pub index: u64,
+    // Shared pages map the cache's frame; private ones copy it on write
+    pub shared: bool,
 }
 
 // Page metadata
@@ -38,6 +66,8 @@ pub struct PageInfo {
     pub ref_count: usize,
     pub flags: PageTableFlags,
     pub cow_source: Option<PhysFrame>,
+    pub zram_handle: Option<usize>,
+    pub file: Option<FilePage>,
 }
 
 impl PageInfo {
@@ -49,6 +79,21 @@ impl PageInfo {
             ref_count: 0,
             flags: PageTableFlags::empty(),
             cow_source: None,
+            zram_handle: None,
+            file: None,
+        }
+    }
+    
+    pub fn new_file(file: FilePage, flags: PageTableFlags) -> Self {
+        Self {
+            state: PageState::File,
+            frame: None,
+            swap_slot: None,
+            ref_count: 0,
+            flags,
+            cow_source: None,
+            zram_handle: None,
+            file: Some(file),
         }
     }
     
@@ -60,129 +105,142 @@ impl PageInfo {
             ref_count: 1,
             flags: flags & !PageTableFlags::WRITABLE, // Remove write permission
             cow_source: Some(source),
+            zram_handle: None,
+            file: None,
         }
     }
 }
 
-// Swap space management
-pub struct SwapManager {
-    swap_file: Vec<[u8; 4096]>,  // Simplified: in-memory swap
-    free_slots: Vec<usize>,
-    used_slots: BTreeMap<usize, Page>,
+// A frame through the physical memory map
+pub(super) fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut u8
 }
 
-impl SwapManager {
-    pub fn new(size_pages: usize) -> Self {
-        let mut free_slots = Vec::new();
-        for i in 0..size_pages {
-            free_slots.push(i);
-        }
-        
-        Self {
-            swap_file: Vec::with_capacity(size_pages),
-            free_slots,
-            used_slots: BTreeMap::new(),
-        }
-    }
-    
-    pub fn allocate_slot(&mut self) -> Option<usize> {
-        self.free_slots.pop()
-    }
-    
-    pub fn free_slot(&mut self, slot: usize) {
-        self.used_slots.remove(&slot);
-        self.free_slots.push(slot);
-    }
-    
-    pub fn swap_out(&mut self, page: Page, data: &[u8; 4096]) -> Option<usize> {
-        let slot = self.allocate_slot()?;
-        
-        // Ensure swap file is large enough
-        while self.swap_file.len() <= slot {
-            self.swap_file.push([0; 4096]);
-        }
-        
-        self.swap_file[slot] = *data;
-        self.used_slots.insert(slot, page);
-        Some(slot)
-    }
-    
-    pub fn swap_in(&mut self, slot: usize) -> Option<[u8; 4096]> {
-        if slot < self.swap_file.len() {
-            let data = self.swap_file[slot];
-            Some(data)
-        } else {
-            None
-        }
-    }
-}
+// Pages evicted per attempt when a fault finds no free frame
+const RECLAIM_BATCH: usize = 32;
 
 // Demand paging manager
 pub struct DemandPagingManager {
-    page_table: BTreeMap<Page, PageInfo>,
-    swap_manager: SwapManager,
+    pub(super) page_table: BTreeMap<Page, PageInfo>,
     zero_frame: PhysFrame,
+    // Where the reclaim clock stopped
+    clock_hand: Option<Page>,
+    // Frames fork left copy-on-write, by how many pages still map them
+    forked_frames: BTreeMap<PhysFrame, usize>,
+    // Pages of forked processes that are not switched in, by process. The
+    // page table holds one process's page at each address; switching swaps
+    // the others' in.
+    detached: BTreeMap<u32, BTreeMap<Page, PageInfo>>,
+    // Whose page the page table holds, where forked processes each have one
+    holders: BTreeMap<Page, u32>,
+    // The process last switched in
+    active: Option<u32>,
 }
 
 impl DemandPagingManager {
-    pub fn new(swap_size: usize) -> Self {
+    pub fn new() -> Self {
         // Allocate a zero frame
         let zero_frame = super::frame_allocator::allocate_frame()
             .expect("Failed to allocate zero frame");
         
         // Clear the zero frame
         unsafe {
-            let ptr = zero_frame.start_address().as_u64() as *mut u8;
-            core::ptr::write_bytes(ptr, 0, 4096);
+            core::ptr::write_bytes(frame_ptr(zero_frame), 0, 4096);
         }
         
         Self {
             page_table: BTreeMap::new(),
-            swap_manager: SwapManager::new(swap_size),
             zero_frame,
+            clock_hand: None,
+            forked_frames: BTreeMap::new(),
+            detached: BTreeMap::new(),
+            holders: BTreeMap::new(),
+            active: None,
         }
     }
     
+    // A free frame, evicting cold pages to make one if there is none, and
+    // killing a process for its memory when nothing can be evicted
+    fn new_frame(&mut self, mapper: &mut (impl Mapper<Size4KiB> + Translate)) -> Result<PhysFrame, &'static str> {
+        if let Some(frame) = super::frame_allocator::allocate_frame() {
+            return Ok(frame);
+        }
+        self.reclaim(RECLAIM_BATCH, mapper);
+        loop {
+            if let Some(frame) = super::frame_allocator::allocate_frame() {
+                return Ok(frame);
+            }
+            if !super::oom::kill(self, mapper) {
+                return Err("Out of memory");
+            }
+        }
+    }
+    
+    // Map `page`, replacing whatever it was mapped to
+    pub(super) fn remap(
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+        // A shadow stack access counts as a write at every level, so its
+        // tables must be writable though the page is not
+        let table_flags = if is_shadow_stack(flags) {
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+        } else {
+            flags & (PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE)
+        };
+        let mut allocator = super::frame_allocator::FRAME_ALLOCATOR.lock();
+        unsafe {
+            mapper.map_to_with_table_flags(page, frame, flags, table_flags, &mut *allocator)
+                .map_err(|_| "Failed to map page")?
+                .flush();
+        }
+        Ok(())
+    }
+    
     // Handle page fault
     pub fn handle_page_fault(
         &mut self,
         addr: VirtAddr,
         error_code: u64,
-        mapper: &mut impl Mapper<Size4KiB>,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
     ) -> Result<(), &'static str> {
         let page = Page::<Size4KiB>::containing_address(addr);
         
         // Check if this is a known page
-        let page_info = self.page_table.get_mut(&page)
-            .ok_or("Page fault on unmapped page")?;
+        let state = self.page_table.get(&page)
+            .ok_or("Page fault on unmapped page")?
+            .state;
         
-        match page_info.state {
+        match state {
             PageState::NotPresent => {
                 return Err("Page not present");
             }
             
             PageState::Zero => {
                 // Allocate a new frame for zero page
-                let frame = super::frame_allocator::allocate_frame()
-                    .ok_or("Out of memory")?;
+                let frame = self.new_frame(mapper)?;
                 
                 // Clear the frame
                 unsafe {
-                    let ptr = frame.start_address().as_u64() as *mut u8;
-                    core::ptr::write_bytes(ptr, 0, 4096);
+                    core::ptr::write_bytes(frame_ptr(frame), 0, 4096);
                 }
                 
-                // Map the page
-                let flags = PageTableFlags::PRESENT 
-                    | PageTableFlags::WRITABLE 
-                    | PageTableFlags::USER_ACCESSIBLE;
+                // Map the page; anonymous mappings carry their own protection
+                let flags = match self.page_table[&page].flags {
+                    flags if flags.is_empty() => PageTableFlags::PRESENT 
+                        | PageTableFlags::WRITABLE 
+                        | PageTableFlags::USER_ACCESSIBLE,
+                    flags => flags | PageTableFlags::PRESENT,
+                };
                 
-                unsafe {
-                    mapper.map_to(page, frame, flags, &mut *super::frame_allocator::FRAME_ALLOCATOR.lock())
-                        .map_err(|_| "Failed to map page")?
-                        .flush();
-                }
+                Self::remap(page, frame, flags, mapper)?;
                 
+                let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
                 page_info.state = PageState::InMemory;
                 page_info.frame = Some(frame);
                 page_info.flags = flags;
@@ -192,51 +250,122 @@ impl DemandPagingManager {
             
             PageState::OnDisk => {
                 // Page is swapped out, bring it back
+                let page_info = &self.page_table[&page];
                 let slot = page_info.swap_slot
                     .ok_or("No swap slot for swapped page")?;
-                
-                let data = self.swap_manager.swap_in(slot)
-                    .ok_or("Failed to swap in page")?;
+                let flags = page_info.flags;
                 
                 // Allocate a new frame
-                let frame = super::frame_allocator::allocate_frame()
-                    .ok_or("Out of memory")?;
+                let frame = self.new_frame(mapper)?;
                 
-                // Copy data to frame
-                unsafe {
-                    let ptr = frame.start_address().as_u64() as *mut [u8; 4096];
-                    *ptr = data;
+                // Read the page into it
+                let read = {
+                    let data = unsafe { &mut *(frame_ptr(frame) as *mut [u8; 4096]) };
+                    super::swap::read_page(slot, data)
+                };
+                if let Err(e) = read {
+                    super::frame_allocator::deallocate_frame(frame);
+                    return Err(e);
                 }
                 
                 // Map the page
-                unsafe {
-                    mapper.map_to(page, frame, page_info.flags | PageTableFlags::PRESENT, 
-                        &mut *super::frame_allocator::FRAME_ALLOCATOR.lock())
-                        .map_err(|_| "Failed to map page")?
-                        .flush();
-                }
+                Self::remap(page, frame, flags | PageTableFlags::PRESENT, mapper)?;
                 
                 // Update page info
+                let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
                 page_info.state = PageState::InMemory;
                 page_info.frame = Some(frame);
-                self.swap_manager.free_slot(slot);
+                super::swap::free_slot(slot);
                 page_info.swap_slot = None;
                 
                 Ok(())
             }
             
+            PageState::Compressed => {
+                let page_info = &self.page_table[&page];
+                let handle = page_info.zram_handle
+                    .ok_or("No zram handle for compressed page")?;
+                let flags = page_info.flags;
+                
+                let frame = self.new_frame(mapper)?;
+                let loaded = {
+                    let data = unsafe { &mut *(frame_ptr(frame) as *mut [u8; 4096]) };
+                    super::zram::ZRAM.lock().load(handle, data)
+                };
+                if let Err(e) = loaded {
+                    super::frame_allocator::deallocate_frame(frame);
+                    return Err(e);
+                }
+                
+                Self::remap(page, frame, flags | PageTableFlags::PRESENT, mapper)?;
+                
+                super::zram::ZRAM.lock().release(handle);
+                let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+                page_info.state = PageState::InMemory;
+                page_info.frame = Some(frame);
+                page_info.zram_handle = None;
+                
+                Ok(())
+            }
+            
+            PageState::File => {
+                let page_info = &self.page_table[&page];
+                let file = page_info.file.clone().ok_or("No file for file page")?;
+                let flags = page_info.flags | PageTableFlags::PRESENT;
+                let write = error_code & PAGE_FAULT_WRITE != 0;
+                if write && !flags.contains(PageTableFlags::WRITABLE) {
+                    return Err("Write to a read-only mapping");
+                }
+                
+                let cached = self.cache_frame(&file, mapper)?;
+                
+                if file.shared {
+                    // Stores land in the cache; the dirty bit says which
+                    Self::remap(page, cached, flags, mapper)?;
+                    let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+                    page_info.state = PageState::InMemory;
+                    page_info.frame = Some(cached);
+                } else if write {
+                    // A private page written at once needs its copy now
+                    let frame = self.new_frame(mapper)?;
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(frame_ptr(cached), frame_ptr(frame), 4096);
+                    }
+                    Self::remap(page, frame, flags, mapper)?;
+                    let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+                    page_info.state = PageState::InMemory;
+                    page_info.frame = Some(frame);
+                    page_info.file = None;
+                } else {
+                    Self::remap(page, cached, flags & !PageTableFlags::WRITABLE, mapper)?;
+                    let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+                    page_info.state = PageState::CopyOnWrite;
+                    page_info.frame = Some(cached);
+                    page_info.cow_source = Some(cached);
+                }
+                
+                Ok(())
+            }
+            
             PageState::CopyOnWrite => {
+                let page_info = &self.page_table[&page];
+                let flags = page_info.flags;
+                
+                // Private file pages and forked ones keep their protection
+                let forked = page_info.cow_source.map_or(false, |source| self.forked_frames.contains_key(&source));
+                if (page_info.file.is_some() || forked)
+                    && error_code & PAGE_FAULT_WRITE != 0
+                    && !flags.contains(PageTableFlags::WRITABLE)
+                {
+                    return Err("Write to a read-only mapping");
+                }
+                
                 // Check if this is a write fault
                 if error_code & PAGE_FAULT_WRITE == 0 {
                     // Read fault on COW page - just map it read-only
                     if let Some(source) = page_info.cow_source {
-                        unsafe {
-                            mapper.map_to(page, source, 
-                                page_info.flags & !PageTableFlags::WRITABLE,
-                                &mut *super::frame_allocator::FRAME_ALLOCATOR.lock())
-                                .map_err(|_| "Failed to map COW page")?
-                                .flush();
-                        }
+                        Self::remap(page, source, flags & !PageTableFlags::WRITABLE, mapper)
+                            .map_err(|_| "Failed to map COW page")?;
                         return Ok(());
                     }
                 }
@@ -245,31 +374,40 @@ impl DemandPagingManager {
                 let source = page_info.cow_source
                     .ok_or("No source for COW page")?;
                 
+                // The last page left on a forked frame takes it over
+                if self.forked_frames.get(&source) == Some(&1) {
+                    self.forked_frames.remove(&source);
+                    Self::remap(page, source, flags | PageTableFlags::WRITABLE | PageTableFlags::PRESENT, mapper)?;
+                    let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+                    page_info.state = PageState::InMemory;
+                    page_info.frame = Some(source);
+                    page_info.cow_source = None;
+                    return Ok(());
+                }
+                
                 // Allocate a new frame
-                let new_frame = super::frame_allocator::allocate_frame()
-                    .ok_or("Out of memory")?;
+                let new_frame = self.new_frame(mapper)?;
                 
                 // Copy the page content
                 unsafe {
-                    let src_ptr = source.start_address().as_u64() as *const u8;
-                    let dst_ptr = new_frame.start_address().as_u64() as *mut u8;
-                    core::ptr::copy_nonoverlapping(src_ptr, dst_ptr, 4096);
+                    core::ptr::copy_nonoverlapping(frame_ptr(source), frame_ptr(new_frame), 4096);
                 }
                 
                 // Map the new frame with write permission
-                unsafe {
-                    mapper.map_to(page, new_frame, 
-                        page_info.flags | PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                        &mut *super::frame_allocator::FRAME_ALLOCATOR.lock())
-                        .map_err(|_| "Failed to map copied page")?
-                        .flush();
-                }
+                Self::remap(page, new_frame, flags | PageTableFlags::WRITABLE | PageTableFlags::PRESENT, mapper)
+                    .map_err(|_| "Failed to map copied page")?;
+                
+                // Give up this page's share of the forked or merged frame
+                self.put_frame(source);
                 
                 // Update page info
+                let page_info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
                 page_info.state = PageState::InMemory;
                 page_info.frame = Some(new_frame);
+                page_info.flags = flags | PageTableFlags::WRITABLE;
                 page_info.cow_source = None;
                 page_info.ref_count = 1;
+                page_info.file = None;
                 
                 Ok(())
             }
@@ -281,6 +419,213 @@ impl DemandPagingManager {
         }
     }
     
+    /// Evict up to `count` resident pages that have not been used since the
+    /// clock last passed them, to zram or else to swap. File pages go back
+    /// to the page cache, which then gives up frames nothing maps. Returns
+    /// how many pages went.
+    pub fn reclaim(&mut self, count: usize, mapper: &mut (impl Mapper<Size4KiB> + Translate)) -> usize {
+        let mut resident: Vec<Page> = self.page_table
+            .iter()
+            .filter(|(_, info)| Self::reclaimable(info))
+            .map(|(page, _)| *page)
+            .collect();
+        // Start just past the hand
+        if let Some(hand) = self.clock_hand {
+            let past = resident.partition_point(|&page| page <= hand);
+            resident.rotate_left(past);
+        }
+        
+        let mut evicted = 0;
+        // Twice round at most: once to clear accessed bits, once to evict
+        for &page in resident.iter().chain(resident.iter()) {
+            if evicted == count {
+                break;
+            }
+            if !self.page_table.get(&page).map_or(false, Self::reclaimable) {
+                continue;
+            }
+            self.clock_hand = Some(page);
+            if let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address()) {
+                if flags.contains(PageTableFlags::ACCESSED) {
+                    if let Ok(flush) = unsafe { mapper.update_flags(page, flags - PageTableFlags::ACCESSED) } {
+                        flush.flush();
+                    }
+                    continue;
+                }
+            }
+            let evict = if self.page_table[&page].file.is_some() {
+                self.drop_file_page(page, mapper)
+            } else {
+                self.compress_page(page, mapper).or_else(|_| self.swap_out_page(page, mapper))
+            };
+            if evict.is_ok() {
+                evicted += 1;
+            }
+        }
+        
+        let (frames, keys) = self.in_use();
+        page_cache::shrink(count, &frames, &keys);
+        EVICTED.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+    
+    // Resident pages reclaim may take: anonymous ones, and file pages
+    // that can be read again from their file
+    fn reclaimable(info: &PageInfo) -> bool {
+        match &info.file {
+            None => info.state == PageState::InMemory,
+            Some(file) => {
+                matches!(info.state, PageState::InMemory | PageState::CopyOnWrite)
+                    && matches!(file.key, FileKey::Path(_))
+            }
+        }
+    }
+    
+    // Frames and page cache objects some page is mapped to
+    fn in_use(&self) -> (BTreeSet<PhysFrame>, BTreeSet<FileKey>) {
+        let mut frames = BTreeSet::new();
+        let mut keys = BTreeSet::new();
+        let detached = self.detached.values().flat_map(|pages| pages.values());
+        for info in self.page_table.values().chain(detached) {
+            frames.extend(info.frame);
+            if let Some(ref file) = info.file {
+                keys.insert(file.key.clone());
+            }
+        }
+        (frames, keys)
+    }
+    
+    // Frame for a file page from the page cache, read in if need be
+    fn cache_frame(
+        &mut self,
+        file: &FilePage,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> Result<PhysFrame, &'static str> {
+        if let Some(frame) = page_cache::lookup(&file.key, file.index) {
+            return Ok(frame);
+        }
+        let frame = self.new_frame(mapper)?;
+        page_cache::fill(&file.key, file.index, frame).map_err(|_| "Failed to read file page")
+    }
+    
+    // Pass a shared file page's dirty bit on to the page cache
+    fn collect_dirty_page(page: Page, info: &PageInfo, mapper: &mut (impl Mapper<Size4KiB> + Translate)) {
+        let Some(ref file) = info.file else { return };
+        if !file.shared || info.state != PageState::InMemory {
+            return;
+        }
+        if let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address()) {
+            if flags.contains(PageTableFlags::DIRTY) {
+                page_cache::mark_dirty(&file.key, file.index);
+                if let Ok(flush) = unsafe { mapper.update_flags(page, flags - PageTableFlags::DIRTY) } {
+                    flush.flush();
+                }
+            }
+        }
+    }
+    
+    // Unmap a file page, leaving it to the page cache until next touched
+    fn drop_file_page(
+        &mut self,
+        page: Page,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> Result<(), &'static str> {
+        let page_info = self.page_table.get_mut(&page).ok_or("Page not found")?;
+        Self::collect_dirty_page(page, page_info, mapper);
+        let (_, flush) = mapper.unmap(page).map_err(|_| "Failed to unmap page")?;
+        flush.flush();
+        page_info.state = PageState::File;
+        page_info.frame = None;
+        page_info.cow_source = None;
+        Ok(())
+    }
+    
+    /// Map `range` to a file or other page cache object from page `first`
+    /// on, each page read in when first touched
+    pub fn map_file(
+        &mut self,
+        range: core::ops::Range<VirtAddr>,
+        key: FileKey,
+        first: u64,
+        shared: bool,
+        flags: PageTableFlags,
+    ) {
+        let pages = Page::range(Page::containing_address(range.start), Page::containing_address(range.end - 1u64) + 1);
+        for (index, page) in (first..).zip(pages) {
+            let file = FilePage { key: key.clone(), index, shared };
+            self.page_table.insert(page, PageInfo::new_file(file, flags));
+        }
+    }
+    
+    /// Map `range` to zeroed memory, each page allocated when first touched
+    pub fn map_anonymous(&mut self, range: core::ops::Range<VirtAddr>, flags: PageTableFlags) {
+        let pages = Page::range(Page::containing_address(range.start), Page::containing_address(range.end - 1u64) + 1);
+        for page in pages {
+            self.page_table.insert(page, PageInfo { flags, ..PageInfo::new_zero() });
+        }
+    }
+    
+    /// Pass the stores to shared file pages in `range` on to the page
+    /// cache. Returns the objects they belong to.
+    pub fn sync_range(
+        &mut self,
+        range: core::ops::Range<VirtAddr>,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> BTreeSet<FileKey> {
+        let first = Page::containing_address(range.start);
+        let mut keys = BTreeSet::new();
+        for (page, info) in self.page_table.range(first..) {
+            if page.start_address() >= range.end {
+                break;
+            }
+            if let Some(ref file) = info.file {
+                if file.shared {
+                    Self::collect_dirty_page(*page, info, mapper);
+                    keys.insert(file.key.clone());
+                }
+            }
+        }
+        keys
+    }
+    
+    // Move a resident page into zram
+    pub fn compress_page(
+        &mut self,
+        page: Page,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        let page_info = self.page_table.get_mut(&page)
+            .ok_or("Page not found")?;
+        
+        if page_info.state != PageState::InMemory {
+            return Err("Page not in memory");
+        }
+        
+        let frame = page_info.frame
+            .ok_or("No frame for in-memory page")?;
+        
+        let handle = {
+            let data = unsafe { &*(frame_ptr(frame) as *const [u8; 4096]) };
+            super::zram::ZRAM.lock().store(data).ok_or("zram full or page incompressible")?
+        };
+        
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(_) => {
+                super::zram::ZRAM.lock().release(handle);
+                return Err("Failed to unmap page");
+            }
+        }
+        
+        super::frame_allocator::deallocate_frame(frame);
+        
+        page_info.state = PageState::Compressed;
+        page_info.frame = None;
+        page_info.zram_handle = Some(handle);
+        
+        Ok(())
+    }
+    
     // Allocate a zero page (lazy allocation)
     pub fn allocate_zero_page(&mut self, page: Page) -> Result<(), &'static str> {
         if self.page_table.contains_key(&page) {
@@ -320,19 +665,20 @@ impl DemandPagingManager {
             .ok_or("No frame for in-memory page")?;
         
         // Read page content
-        let data = unsafe {
-            let ptr = frame.start_address().as_u64() as *const [u8; 4096];
-            *ptr
-        };
+        let data = unsafe { *(frame_ptr(frame) as *const [u8; 4096]) };
         
         // Swap to disk
-        let slot = self.swap_manager.swap_out(page, &data)
-            .ok_or("Failed to allocate swap slot")?;
+        let slot = super::swap::write_page(&data)
+            .ok_or("No room in swap")?;
         
         // Unmap the page
-        mapper.unmap(page)
-            .map_err(|_| "Failed to unmap page")?
-            .1.flush();
+        match mapper.unmap(page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(_) => {
+                super::swap::free_slot(slot);
+                return Err("Failed to unmap page");
+            }
+        }
         
         // Free the frame
         super::frame_allocator::deallocate_frame(frame);
@@ -345,37 +691,333 @@ impl DemandPagingManager {
         Ok(())
     }
     
-    // Fork a process's memory (for COW)
-    pub fn fork_memory_space(
+    /// Read back every page swapped out to area `area`, for swapoff
+    pub fn swap_in_area(
         &mut self,
-        parent_pages: &BTreeMap<Page, PageInfo>,
-    ) -> BTreeMap<Page, PageInfo> {
-        let mut child_pages = BTreeMap::new();
+        area: usize,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> Result<usize, &'static str> {
+        let pages: Vec<Page> = self.page_table
+            .iter()
+            .filter(|(_, info)| {
+                info.state == PageState::OnDisk && info.swap_slot.map(super::swap::area_of) == Some(area)
+            })
+            .map(|(page, _)| *page)
+            .collect();
+        for &page in &pages {
+            self.handle_page_fault(page.start_address(), 0, mapper)?;
+        }
         
-        for (page, info) in parent_pages {
+        // Switched-out pages are read in now and mapped when switched in
+        let detached: Vec<(u32, Page, usize)> = self.detached
+            .iter()
+            .flat_map(|(&pid, pages)| pages.iter().map(move |(&page, info)| (pid, page, info)))
+            .filter_map(|(pid, page, info)| match (info.state, info.swap_slot) {
+                (PageState::OnDisk, Some(slot)) if super::swap::area_of(slot) == area => Some((pid, page, slot)),
+                _ => None,
+            })
+            .collect();
+        for &(pid, page, slot) in &detached {
+            let frame = self.new_frame(mapper)?;
+            // Making room may have killed the page's process
+            let Some(info) = self.detached.get_mut(&pid).and_then(|pages| pages.get_mut(&page)) else {
+                super::frame_allocator::deallocate_frame(frame);
+                continue;
+            };
+            let read = {
+                let data = unsafe { &mut *(frame_ptr(frame) as *mut [u8; 4096]) };
+                super::swap::read_page(slot, data)
+            };
+            if let Err(e) = read {
+                super::frame_allocator::deallocate_frame(frame);
+                return Err(e);
+            }
+            super::swap::free_slot(slot);
+            info.state = PageState::InMemory;
+            info.frame = Some(frame);
+            info.swap_slot = None;
+        }
+        Ok(pages.len() + detached.len())
+    }
+    
+    /// Pages in `range` that are resident (or merged), and that are
+    /// compressed or swapped out
+    pub fn usage(&self, range: core::ops::Range<VirtAddr>) -> (usize, usize) {
+        let first = Page::containing_address(range.start);
+        let mut usage = (0, 0);
+        for (page, info) in self.page_table.range(first..) {
+            if page.start_address() >= range.end {
+                break;
+            }
             match info.state {
-                PageState::InMemory => {
-                    // Set up COW for both parent and child
-                    if let Some(frame) = info.frame {
-                        let child_info = PageInfo::new_cow(frame, info.flags);
-                        child_pages.insert(*page, child_info);
-                        
-                        // Note: In real implementation, would also update parent's page
-                        // to be COW and increase reference count
-                    }
+                PageState::InMemory | PageState::CopyOnWrite => usage.0 += 1,
+                PageState::Compressed | PageState::OnDisk => usage.1 += 1,
+                _ => {}
+            }
+        }
+        usage
+    }
+    
+    /// Drop process `pid`'s pages in `range`, freeing frames, compressed
+    /// copies and swap slots; file pages are left to the page cache, with
+    /// their stores. Returns the frames freed.
+    pub fn release_range(
+        &mut self,
+        pid: u32,
+        range: core::ops::Range<VirtAddr>,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> usize {
+        let first = Page::containing_address(range.start);
+        let in_range = |page: &Page| page.start_address() < range.end;
+        // Pages switched out for a process forked from or by `pid` are theirs
+        let pages: Vec<Page> = self.page_table
+            .range(first..)
+            .map(|(page, _)| *page)
+            .take_while(in_range)
+            .filter(|page| self.holders.get(page).map_or(true, |&holder| holder == pid))
+            .collect();
+        let mut freed = 0;
+        for page in pages {
+            self.holders.remove(&page);
+            let Some(info) = self.page_table.remove(&page) else { continue };
+            if matches!(info.state, PageState::InMemory | PageState::CopyOnWrite) {
+                Self::collect_dirty_page(page, &info, mapper);
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+            }
+            freed += self.release_page(info);
+        }
+        
+        let detached: Vec<PageInfo> = match self.detached.get_mut(&pid) {
+            Some(pages) => {
+                let taken: Vec<Page> = pages.range(first..).map(|(page, _)| *page).take_while(in_range).collect();
+                taken.iter().filter_map(|page| pages.remove(page)).collect()
+            }
+            None => Vec::new(),
+        };
+        for info in detached {
+            freed += self.release_page(info);
+        }
+        freed
+    }
+    
+    // Free what an unmapped page holds. Returns the frames freed.
+    fn release_page(&mut self, info: PageInfo) -> usize {
+        if info.file.is_some() {
+            return 0;
+        }
+        match info.state {
+            PageState::InMemory => {
+                if let Some(frame) = info.frame {
+                    super::frame_allocator::deallocate_frame(frame);
+                    return 1;
+                }
+            }
+            PageState::CopyOnWrite => {
+                if let Some(source) = info.cow_source {
+                    self.put_frame(source);
+                }
+            }
+            PageState::Compressed => {
+                if let Some(handle) = info.zram_handle {
+                    super::zram::ZRAM.lock().release(handle);
+                }
+            }
+            PageState::OnDisk => {
+                if let Some(slot) = info.swap_slot {
+                    super::swap::free_slot(slot);
+                }
+            }
+            _ => {}
+        }
+        0
+    }
+    
+    // Drop a copy-on-write page's share of its frame. Forked frames go
+    // with their last page; merged ones are ksm's to free.
+    fn put_frame(&mut self, frame: PhysFrame) {
+        if let Some(count) = self.forked_frames.get_mut(&frame) {
+            *count -= 1;
+            if *count > 0 {
+                return;
+            }
+            self.forked_frames.remove(&frame);
+            if !super::ksm::merged(frame) {
+                super::frame_allocator::deallocate_frame(frame);
+                return;
+            }
+        }
+        super::ksm::unshare(frame);
+    }
+    
+    /// Give process `child` a copy-on-write copy of process `parent`'s
+    /// pages in `ranges`. Resident pages are shared read-only until one
+    /// side writes, but for shadow stacks, which a shadow stack write
+    /// cannot fault out of sharing and so are copied now; compressed and swapped-out pages are duplicated, and
+    /// file pages read again from the page cache. The child's pages are
+    /// switched in by `switch_to`.
+    pub fn fork(
+        &mut self,
+        parent: u32,
+        child: u32,
+        ranges: &[core::ops::Range<VirtAddr>],
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> Result<(), &'static str> {
+        self.switch_to(parent, mapper);
+        let mut pages: Vec<Page> = Vec::new();
+        for range in ranges.iter().filter(|range| range.start < range.end) {
+            let first = Page::containing_address(range.start);
+            pages.extend(
+                self.page_table
+                    .range(first..)
+                    .map(|(page, _)| *page)
+                    .take_while(|page| page.start_address() < range.end)
+                    .filter(|page| self.holders.get(page).map_or(true, |&holder| holder == parent)),
+            );
+        }
+        pages.sort();
+        pages.dedup();
+        
+        // Copies that can fail come first, so that failing leaves the
+        // parent as it was
+        let mut copies = BTreeMap::new();
+        for &page in &pages {
+            let info = &self.page_table[&page];
+            if info.file.is_some() {
+                continue;
+            }
+            let copy = match info.state {
+                PageState::Compressed => Self::copy_compressed(info),
+                PageState::OnDisk => Self::copy_swapped(info),
+                PageState::InMemory if is_shadow_stack(info.flags) => {
+                    let info = info.clone();
+                    self.copy_resident(&info, mapper)
                 }
-                PageState::Zero => {
-                    // Share zero pages
-                    child_pages.insert(*page, PageInfo::new_zero());
+                _ => continue,
+            };
+            match copy {
+                Ok(copy) => {
+                    copies.insert(page, copy);
                 }
-                _ => {
-                    // Copy other page states
-                    child_pages.insert(*page, info.clone());
+                Err(e) => {
+                    for (_, copy) in copies {
+                        self.release_page(copy);
+                    }
+                    return Err(e);
                 }
             }
         }
         
-        child_pages
+        let mut child_pages = BTreeMap::new();
+        for page in pages {
+            let info = self.page_table.get_mut(&page).ok_or("Page vanished")?;
+            let copy = match (&info.file, info.state) {
+                (Some(file), _) => PageInfo::new_file(file.clone(), info.flags),
+                (None, _) if copies.contains_key(&page) => copies.remove(&page).ok_or("Page vanished")?,
+                (None, PageState::InMemory) => {
+                    let frame = info.frame.ok_or("No frame for in-memory page")?;
+                    if let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address()) {
+                        if let Ok(flush) = unsafe { mapper.update_flags(page, flags - PageTableFlags::WRITABLE) } {
+                            flush.flush();
+                        }
+                    }
+                    info.state = PageState::CopyOnWrite;
+                    info.cow_source = Some(frame);
+                    *self.forked_frames.entry(frame).or_insert(1) += 1;
+                    info.clone()
+                }
+                (None, PageState::CopyOnWrite) => {
+                    let source = info.cow_source.ok_or("No source for COW page")?;
+                    *self.forked_frames.entry(source).or_insert(1) += 1;
+                    info.clone()
+                }
+                _ => info.clone(),
+            };
+            self.holders.insert(page, parent);
+            child_pages.insert(page, copy);
+        }
+        self.detached.insert(child, child_pages);
+        Ok(())
+    }
+    
+    fn copy_resident(
+        &mut self,
+        info: &PageInfo,
+        mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    ) -> Result<PageInfo, &'static str> {
+        let source = info.frame.ok_or("No frame for in-memory page")?;
+        let frame = self.new_frame(mapper)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame_ptr(source), frame_ptr(frame), 4096);
+        }
+        Ok(PageInfo { frame: Some(frame), ..info.clone() })
+    }
+    
+    fn copy_compressed(info: &PageInfo) -> Result<PageInfo, &'static str> {
+        let handle = info.zram_handle.ok_or("No zram handle for compressed page")?;
+        let mut data = [0u8; 4096];
+        let mut zram = super::zram::ZRAM.lock();
+        zram.load(handle, &mut data)?;
+        let copy = zram.store(&data).ok_or("zram full")?;
+        Ok(PageInfo { zram_handle: Some(copy), ..info.clone() })
+    }
+    
+    fn copy_swapped(info: &PageInfo) -> Result<PageInfo, &'static str> {
+        let slot = info.swap_slot.ok_or("No swap slot for swapped page")?;
+        let mut data = [0u8; 4096];
+        super::swap::read_page(slot, &mut data)?;
+        let copy = super::swap::write_page(&data).ok_or("No room in swap")?;
+        Ok(PageInfo { swap_slot: Some(copy), ..info.clone() })
+    }
+    
+    /// Switch in process `pid`'s pages where forked processes have pages at
+    /// the same addresses, switching out whoever's were there
+    pub fn switch_to(&mut self, pid: u32, mapper: &mut (impl Mapper<Size4KiB> + Translate)) {
+        let previous = self.active.replace(pid);
+        let Some(pages) = self.detached.remove(&pid) else {
+            return;
+        };
+        for (page, info) in pages {
+            if let Some(current) = self.page_table.remove(&page) {
+                if matches!(current.state, PageState::InMemory | PageState::CopyOnWrite) {
+                    Self::collect_dirty_page(page, &current, mapper);
+                    if let Ok((_, flush)) = mapper.unmap(page) {
+                        flush.flush();
+                    }
+                }
+                let holder = self.holders.get(&page).copied().or(previous).unwrap_or(0);
+                self.detached.entry(holder).or_default().insert(page, current);
+            }
+            let mapped = match info.state {
+                PageState::InMemory => info.frame.map(|frame| (frame, info.flags | PageTableFlags::PRESENT)),
+                PageState::CopyOnWrite => info
+                    .cow_source
+                    .map(|source| (source, (info.flags | PageTableFlags::PRESENT) & !PageTableFlags::WRITABLE)),
+                _ => None,
+            };
+            if let Some((frame, flags)) = mapped {
+                // Left unmapped, the page faults and is mapped then
+                let _ = Self::remap(page, frame, flags, mapper);
+            }
+            self.holders.insert(page, pid);
+            self.page_table.insert(page, info);
+        }
+    }
+    
+    /// Drop whatever pages an exiting process still has switched out, or
+    /// switched in where forked processes share addresses
+    pub fn release_process(&mut self, pid: u32, mapper: &mut (impl Mapper<Size4KiB> + Translate)) {
+        let held: Vec<Page> = self.holders.iter().filter(|(_, &holder)| holder == pid).map(|(page, _)| *page).collect();
+        for page in held {
+            self.release_range(pid, page.start_address()..page.start_address() + 4096u64, mapper);
+        }
+        for (_, info) in self.detached.remove(&pid).unwrap_or_default() {
+            self.release_page(info);
+        }
+        if self.active == Some(pid) {
+            self.active = None;
+        }
     }
 }
 
@@ -384,35 +1026,78 @@ lazy_static! {
     pub static ref DEMAND_PAGING: Mutex<Option<DemandPagingManager>> = Mutex::new(None);
 }
 
-// Initialize demand paging
-pub fn init_demand_paging(swap_size_mb: usize) {
-    let swap_pages = swap_size_mb * 256; // 256 pages per MB
-    let manager = DemandPagingManager::new(swap_pages);
+// Initialize demand paging; swap areas are added later with swapon
+pub fn init_demand_paging() {
+    let manager = DemandPagingManager::new();
     *DEMAND_PAGING.lock() = Some(manager);
-    crate::serial_println!("Demand paging initialized with {}MB swap", swap_size_mb);
+    crate::serial_println!("Demand paging initialized");
+}
+
+// The page tables in use, through the physical memory map
+pub(super) unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+    let (level_4_table_frame, _) = Cr3::read();
+    let page_table_ptr = frame_ptr(level_4_table_frame) as *mut PageTable;
+    OffsetPageTable::new(&mut *page_table_ptr, VirtAddr::new(PHYS_MEM_OFFSET))
 }
 
 // Handle page fault from interrupt handler
 pub fn handle_page_fault(addr: VirtAddr, error_code: u64) -> Result<(), &'static str> {
     let mut demand_paging = DEMAND_PAGING.lock();
     if let Some(ref mut manager) = *demand_paging {
-        // Get active page table
-        use x86_64::registers::control::Cr3;
-        let (level_4_table_frame, _) = Cr3::read();
-        let phys = level_4_table_frame.start_address();
-        let virt = VirtAddr::new(phys.as_u64());
-        let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-        
-        unsafe {
-            use x86_64::structures::paging::OffsetPageTable;
-            let mut mapper = OffsetPageTable::new(
-                &mut *page_table_ptr,
-                VirtAddr::new(0),
-            );
-            
-            manager.handle_page_fault(addr, error_code, &mut mapper)
-        }
+        let mut mapper = unsafe { active_mapper() };
+        manager.handle_page_fault(addr, error_code, &mut mapper)
     } else {
         Err("Demand paging not initialized")
     }
-}
\ No newline at end of file
+}
+
+/// Switch in a process's pages before it runs; false if demand paging is
+/// busy, as when the timer interrupts a fault
+pub fn switch_to(pid: u32) -> bool {
+    let Some(mut demand_paging) = DEMAND_PAGING.try_lock() else {
+        return false;
+    };
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        manager.switch_to(pid, &mut mapper);
+    }
+    true
+}
+
+/// Map a user shadow stack, each page allocated when first touched
+pub fn map_shadow_stack(range: core::ops::Range<VirtAddr>) {
+    if let Some(ref mut manager) = *DEMAND_PAGING.lock() {
+        manager.map_anonymous(range, SHADOW_STACK_FLAGS);
+    }
+}
+
+/// Pass every store to a shared file mapping on to the page cache
+pub fn collect_dirty() {
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        manager.sync_range(VirtAddr::new(0)..VirtAddr::new(super::userspace::USER_SPACE_END), &mut mapper);
+    }
+}
+
+static EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Pages evicted by reclaim since boot
+pub fn evicted_pages() -> u64 {
+    EVICTED.load(Ordering::Relaxed)
+}
+
+/// Evict cold pages while free memory is below 1/32 of the total, until it
+/// is back to 1/16
+pub fn poll() {
+    let (total, free, _) = super::frame_allocator::memory_stats();
+    if free >= total / 32 {
+        return;
+    }
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        manager.reclaim(total / 16 - free, &mut mapper);
+    }
+}
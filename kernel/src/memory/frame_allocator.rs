@@ -9,6 +9,8 @@ use spin::Mutex;
 use lazy_static::lazy_static;
 use alloc::vec::Vec;
 
+use crate::numa::MemPolicy;
+
 // Memory map regions
 #[derive(Debug, Clone, Copy)]
 pub struct MemoryRegion {
@@ -16,6 +18,41 @@ pub struct MemoryRegion {
     pub end: PhysAddr,
 }
 
+/// A NUMA node's physical memory and where it is from, for `set_nodes`
+pub struct NodeMemory {
+    pub node: u32,
+    pub ranges: Vec<(u64, u64)>,
+    /// Every node, nearest first, starting with this one
+    pub fallback: Vec<u32>,
+}
+
+/// Allocation counts for a node, as Linux's numastat keeps them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeStats {
+    pub node: u32,
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Allocated here, as intended
+    pub hit: u64,
+    /// Allocated here, though another node was intended
+    pub miss: u64,
+    /// Intended for here, but allocated on another node
+    pub foreign: u64,
+    /// Interleaved allocations that got the node they were meant for
+    pub interleave_hit: u64,
+    /// Allocated here by a CPU on this node, and by one elsewhere
+    pub local_node: u64,
+    pub other_node: u64,
+}
+
+// A node's frames, by frame number
+struct NodeFrames {
+    ranges: Vec<(usize, usize)>,
+    fallback: Vec<u32>,
+    next_free: usize,
+    stats: NodeStats,
+}
+
 // Bitmap frame allocator
 pub struct BitmapFrameAllocator {
     bitmap: Vec<u64>,
@@ -23,6 +60,9 @@ pub struct BitmapFrameAllocator {
     total_frames: usize,
     free_frames: usize,
     memory_regions: Vec<MemoryRegion>,
+    // Empty on a machine with one node
+    nodes: Vec<NodeFrames>,
+    policy: MemPolicy,
 }
 
 impl BitmapFrameAllocator {
@@ -33,6 +73,8 @@ impl BitmapFrameAllocator {
             total_frames: 0,
             free_frames: 0,
             memory_regions: Vec::new(),
+            nodes: Vec::new(),
+            policy: MemPolicy::Local,
         }
     }
     
@@ -83,6 +125,9 @@ impl BitmapFrameAllocator {
             self.bitmap[bitmap_idx] &= !(1 << bit_idx);
             if was_used {
                 self.free_frames += 1;
+                if let Some(node) = self.node_of(frame_num) {
+                    self.nodes[node].stats.free_frames += 1;
+                }
             }
         }
     }
@@ -96,6 +141,9 @@ impl BitmapFrameAllocator {
             self.bitmap[bitmap_idx] |= 1 << bit_idx;
             if was_free && self.free_frames > 0 {
                 self.free_frames -= 1;
+                if let Some(node) = self.node_of(frame_num) {
+                    self.nodes[node].stats.free_frames -= 1;
+                }
             }
         }
     }
@@ -111,7 +159,144 @@ impl BitmapFrameAllocator {
         }
     }
     
+    /// A frame placed by the default memory policy
     pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let policy = self.policy;
+        self.allocate_frame_policy(policy)
+    }
+    
+    /// A frame placed by `policy`; without NUMA nodes any frame will do
+    pub fn allocate_frame_policy(&mut self, policy: MemPolicy) -> Option<PhysFrame> {
+        if self.nodes.is_empty() {
+            return self.allocate_any_frame();
+        }
+        let placement = policy.placement();
+        let intended = (placement.node as usize).min(self.nodes.len() - 1);
+        for index in 0..self.nodes[intended].fallback.len() {
+            let node = self.nodes[intended].fallback[index] as usize;
+            if placement.allowed & (1 << node) == 0 {
+                continue;
+            }
+            if let Some(frame_num) = self.find_on_node(node) {
+                self.mark_frame_used(frame_num);
+                self.nodes[node].next_free = frame_num + 1;
+                self.count(intended, node, placement.interleaved);
+                return Some(PhysFrame::containing_address(PhysAddr::new(frame_num as u64 * 4096)));
+            }
+        }
+        // Memory no node claims is the last resort, except for a bound policy
+        match policy {
+            MemPolicy::Bind(_) => None,
+            _ => self.allocate_any_frame(),
+        }
+    }
+    
+    /// A frame on `node` only
+    pub fn allocate_frame_on(&mut self, node: u32) -> Option<PhysFrame> {
+        self.allocate_frame_policy(MemPolicy::Bind(1 << node))
+    }
+    
+    /// `count` contiguous frames, on `node` if there is room there
+    pub fn allocate_contiguous(&mut self, count: usize, node: Option<u32>) -> Option<PhysFrame> {
+        let ranges: Vec<(usize, usize)> = match node.and_then(|node| self.nodes.get(node as usize)) {
+            Some(frames) => frames.ranges.clone(),
+            None => alloc::vec![(0, self.total_frames)],
+        };
+        for (start, end) in ranges {
+            let mut run = 0;
+            for frame_num in start..end {
+                run = if self.is_frame_free(frame_num) { run + 1 } else { 0 };
+                if run == count {
+                    let first = frame_num + 1 - count;
+                    for used in first..=frame_num {
+                        self.mark_frame_used(used);
+                    }
+                    if let Some(node) = node.filter(|&node| (node as usize) < self.nodes.len()) {
+                        self.count(node as usize, node as usize, false);
+                    }
+                    return Some(PhysFrame::containing_address(PhysAddr::new(first as u64 * 4096)));
+                }
+            }
+        }
+        None
+    }
+    
+    fn find_on_node(&self, node: usize) -> Option<usize> {
+        let frames = &self.nodes[node];
+        if frames.stats.free_frames == 0 {
+            return None;
+        }
+        // From the hint to the end of the node, then the rest of it
+        let hint = frames.next_free;
+        let after = frames.ranges.iter().map(|&(start, end)| (start.max(hint), end));
+        let before = frames.ranges.iter().map(|&(start, end)| (start, end.min(hint)));
+        after.chain(before).flat_map(|(start, end)| start..end).find(|&frame_num| self.is_frame_free(frame_num))
+    }
+    
+    fn count(&mut self, intended: usize, got: usize, interleaved: bool) {
+        let local = crate::numa::current_node() as usize == got;
+        let stats = &mut self.nodes[got].stats;
+        if local {
+            stats.local_node += 1;
+        } else {
+            stats.other_node += 1;
+        }
+        if intended == got {
+            stats.hit += 1;
+            if interleaved {
+                stats.interleave_hit += 1;
+            }
+        } else {
+            stats.miss += 1;
+            self.nodes[intended].stats.foreign += 1;
+        }
+    }
+    
+    fn node_of(&self, frame_num: usize) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.ranges.iter().any(|&(start, end)| frame_num >= start && frame_num < end))
+    }
+    
+    /// Tell the allocator which frames are on which node. Frames no node
+    /// claims are only handed out when the nodes allowed are full.
+    pub fn set_nodes(&mut self, nodes: &[NodeMemory]) {
+        self.nodes.clear();
+        if nodes.len() <= 1 {
+            return;
+        }
+        for node in nodes {
+            let ranges: Vec<(usize, usize)> = node
+                .ranges
+                .iter()
+                .map(|&(start, end)| (((start + 4095) / 4096) as usize, ((end / 4096) as usize).min(self.total_frames)))
+                .filter(|(start, end)| start < end)
+                .collect();
+            let total: usize = ranges.iter().map(|(start, end)| end - start).sum();
+            let free = ranges.iter().flat_map(|&(start, end)| start..end).filter(|&f| self.is_frame_free(f)).count();
+            self.nodes.push(NodeFrames {
+                next_free: ranges.first().map_or(0, |&(start, _)| start),
+                ranges,
+                fallback: node.fallback.clone(),
+                stats: NodeStats { node: node.node, total_frames: total, free_frames: free, ..NodeStats::default() },
+            });
+        }
+    }
+    
+    pub fn set_policy(&mut self, policy: MemPolicy) {
+        self.policy = policy;
+    }
+    
+    pub fn policy(&self) -> MemPolicy {
+        self.policy
+    }
+    
+    /// Per-node counts, empty on a machine with one node
+    pub fn node_stats(&self) -> Vec<NodeStats> {
+        self.nodes.iter().map(|node| node.stats).collect()
+    }
+    
+    fn allocate_any_frame(&mut self) -> Option<PhysFrame> {
         // Start searching from next_free
         for frame_num in self.next_free..self.total_frames {
             if self.is_frame_free(frame_num) {
@@ -179,6 +364,11 @@ pub fn allocate_frame() -> Option<PhysFrame> {
     FRAME_ALLOCATOR.lock().allocate_frame()
 }
 
+/// Allocate a frame placed by a memory policy other than the default
+pub fn allocate_frame_policy(policy: MemPolicy) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().allocate_frame_policy(policy)
+}
+
 // Deallocate a physical frame
 pub fn deallocate_frame(frame: PhysFrame) {
     FRAME_ALLOCATOR.lock().deallocate_frame(frame);
@@ -190,26 +380,11 @@ pub fn memory_stats() -> (usize, usize, usize) {
     (allocator.total_frames, allocator.free_frames, allocator.used_frames())
 }
 
-// Bootloader memory map entry types
-pub const USABLE_MEMORY: u32 = 1;
-pub const RESERVED_MEMORY: u32 = 2;
-pub const ACPI_RECLAIMABLE: u32 = 3;
-pub const ACPI_NVS: u32 = 4;
-pub const BAD_MEMORY: u32 = 5;
-
-// Parse bootloader memory map
-pub fn parse_memory_map(boot_info: &[u8]) -> Vec<MemoryRegion> {
-    let mut regions = Vec::new();
-    
-    // This is a simplified version - in reality, would parse actual boot info
-    // For now, assume some standard memory regions
-    
-    // First MB is typically reserved
-    // Usable memory from 1MB to 10MB for testing
-    regions.push(MemoryRegion {
-        start: PhysAddr::new(0x100000),  // 1MB
-        end: PhysAddr::new(0xA00000),    // 10MB
-    });
-    
-    regions
-}
\ No newline at end of file
+// Free RAM from the loader's memory map
+pub fn usable_regions(boot_info: &crate::boot::BootInfo) -> Vec<MemoryRegion> {
+    boot_info
+        .usable_memory()
+        .into_iter()
+        .map(|(start, end)| MemoryRegion { start: PhysAddr::new(start), end: PhysAddr::new(end) })
+        .collect()
+}
@@ -0,0 +1,268 @@
+//! Same-page merging
+//!
+//! When on, a scan walks the pages demand paging has resident, a few at a
+//! time from the main loop, and maps pages with the same contents to one
+//! read-only frame, copy-on-write. A page must be unchanged since the last
+//! pass before it is merged, so pages being written are left alone. As on
+//! Linux, merged frames are kept in a stable tree by checksum, and pages
+//! seen once in an unstable one that starts over each full pass.
+//!
+//! Off unless `ksm` is given; the shell's `ksm on` starts it later.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+
+use super::demand_paging::{frame_ptr, is_shadow_stack, DemandPagingManager, PageInfo, PageState, DEMAND_PAGING};
+use crate::boot::cmdline::Param;
+
+static ENABLED: Param<bool> = Param::new("ksm", false, "Merge identical pages copy-on-write");
+static PAGES_TO_SCAN: Param<i64> = Param::new("ksm.pages_to_scan", 100, "Pages same-page merging scans each time");
+static SLEEP_MS: Param<i64> = Param::new("ksm.sleep_ms", 20, "Milliseconds between same-page merging scans");
+
+static RUN: AtomicBool = AtomicBool::new(false);
+static NEXT_SCAN: AtomicU64 = AtomicU64::new(0);
+
+/// Counts as Linux's /sys/kernel/mm/ksm has them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KsmStats {
+    /// Merged frames in use
+    pub pages_shared: usize,
+    /// Pages mapped to merged frames beyond the first of each; the saving
+    pub pages_sharing: usize,
+    /// Pages waiting in the unstable tree for a twin
+    pub pages_unshared: usize,
+    /// Pages skipped because they changed since the last pass
+    pub pages_volatile: u64,
+    pub full_scans: u64,
+}
+
+struct Ksm {
+    // Merged frames by the checksum of their contents
+    stable: BTreeMap<u64, Vec<PhysFrame>>,
+    // Pages mapped to each merged frame, and its checksum
+    sharers: BTreeMap<PhysFrame, (usize, u64)>,
+    unstable: BTreeMap<u64, Page>,
+    // Each page's checksum when the scan last passed it
+    checksums: BTreeMap<Page, u64>,
+    cursor: Option<Page>,
+    pages_volatile: u64,
+    full_scans: u64,
+}
+
+static KSM: Mutex<Ksm> = Mutex::new(Ksm {
+    stable: BTreeMap::new(),
+    sharers: BTreeMap::new(),
+    unstable: BTreeMap::new(),
+    checksums: BTreeMap::new(),
+    cursor: None,
+    pages_volatile: 0,
+    full_scans: 0,
+});
+
+fn contents(frame: PhysFrame) -> &'static [u8; 4096] {
+    unsafe { &*(frame_ptr(frame) as *const [u8; 4096]) }
+}
+
+// FNV-1a over the page, a word at a time
+fn checksum(frame: PhysFrame) -> u64 {
+    contents(frame).chunks_exact(8).fold(0xcbf2_9ce4_8422_2325, |hash, word| {
+        (hash ^ u64::from_ne_bytes(word.try_into().unwrap())).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+// Whether a page may be merged: resident and anonymous, as file pages
+// belong to the page cache, and no shadow stack, which sharing cannot
+// protect
+fn mergeable(info: &PageInfo) -> bool {
+    info.state == PageState::InMemory && info.file.is_none() && !is_shadow_stack(info.flags)
+}
+
+// A mergeable page's frame
+fn resident_frame(manager: &DemandPagingManager, page: Page) -> Option<PhysFrame> {
+    let info = manager.page_table.get(&page)?;
+    if mergeable(info) { info.frame } else { None }
+}
+
+impl Ksm {
+    // Map `page` to the merged frame `shared` and free its own frame
+    fn merge(
+        &mut self,
+        manager: &mut DemandPagingManager,
+        page: Page,
+        shared: PhysFrame,
+        mapper: &mut impl Mapper<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        let info = manager.page_table.get_mut(&page).ok_or("Page vanished")?;
+        let own = info.frame.ok_or("No frame for in-memory page")?;
+        DemandPagingManager::remap(page, shared, info.flags & !PageTableFlags::WRITABLE, mapper)?;
+        info.state = PageState::CopyOnWrite;
+        info.frame = Some(shared);
+        info.cow_source = Some(shared);
+        if own != shared {
+            super::frame_allocator::deallocate_frame(own);
+        }
+        self.sharers.entry(shared).or_insert((0, 0)).0 += 1;
+        self.checksums.remove(&page);
+        Ok(())
+    }
+
+    fn scan_page(&mut self, manager: &mut DemandPagingManager, page: Page, mapper: &mut impl Mapper<Size4KiB>) {
+        let Some(frame) = resident_frame(manager, page) else {
+            return;
+        };
+        let sum = checksum(frame);
+        match self.checksums.insert(page, sum) {
+            Some(previous) if previous == sum => {}
+            Some(_) => {
+                self.pages_volatile += 1;
+                return;
+            }
+            // Seen for the first time
+            None => return,
+        }
+
+        let same = |other: &PhysFrame| contents(*other) == contents(frame);
+        if let Some(&shared) = self.stable.get(&sum).and_then(|frames| frames.iter().find(|other| same(*other))) {
+            let _ = self.merge(manager, page, shared, mapper);
+            return;
+        }
+
+        match self.unstable.get(&sum).copied() {
+            Some(twin) if twin != page => {
+                self.unstable.remove(&sum);
+                // The twin's frame becomes the merged one, if it still matches
+                let Some(shared) = resident_frame(manager, twin).filter(same) else {
+                    self.unstable.insert(sum, page);
+                    return;
+                };
+                if self.merge(manager, twin, shared, mapper).is_ok() {
+                    self.sharers.insert(shared, (1, sum));
+                    self.stable.entry(sum).or_default().push(shared);
+                    let _ = self.merge(manager, page, shared, mapper);
+                }
+            }
+            _ => {
+                self.unstable.insert(sum, page);
+            }
+        }
+    }
+
+    fn scan(&mut self, manager: &mut DemandPagingManager, budget: usize, mapper: &mut impl Mapper<Size4KiB>) {
+        use core::ops::Bound::{Excluded, Unbounded};
+
+        let start = self.cursor.map_or(Unbounded, Excluded);
+        let batch: Vec<Page> = manager
+            .page_table
+            .range((start, Unbounded))
+            .filter(|(_, info)| mergeable(info))
+            .map(|(page, _)| *page)
+            .take(budget)
+            .collect();
+        for &page in &batch {
+            self.scan_page(manager, page, mapper);
+        }
+        self.cursor = batch.last().copied();
+        if batch.len() < budget {
+            // End of a pass; forget pages that have gone
+            self.cursor = None;
+            self.unstable.clear();
+            self.checksums.retain(|page, _| resident_frame(manager, *page).is_some());
+            self.full_scans += 1;
+        }
+    }
+
+    fn stats(&self) -> KsmStats {
+        let sharing: usize = self.sharers.values().map(|(count, _)| count).sum();
+        KsmStats {
+            pages_shared: self.sharers.len(),
+            pages_sharing: sharing - self.sharers.len(),
+            pages_unshared: self.unstable.len(),
+            pages_volatile: self.pages_volatile,
+            full_scans: self.full_scans,
+        }
+    }
+}
+
+/// A page mapped to `frame` no longer is. The last one out frees a merged
+/// frame; frames not merged here are left alone.
+pub fn unshare(frame: PhysFrame) {
+    let mut ksm = KSM.lock();
+    let Some((count, sum)) = ksm.sharers.get_mut(&frame).map(|entry| {
+        entry.0 -= 1;
+        *entry
+    }) else {
+        return;
+    };
+    if count > 0 {
+        return;
+    }
+    ksm.sharers.remove(&frame);
+    if let Some(frames) = ksm.stable.get_mut(&sum) {
+        frames.retain(|other| *other != frame);
+        if frames.is_empty() {
+            ksm.stable.remove(&sum);
+        }
+    }
+    super::frame_allocator::deallocate_frame(frame);
+}
+
+/// Whether `frame` is one merged pages share
+pub fn merged(frame: PhysFrame) -> bool {
+    KSM.lock().sharers.contains_key(&frame)
+}
+
+pub fn set_running(on: bool) {
+    RUN.store(on, Ordering::Relaxed);
+}
+
+pub fn running() -> bool {
+    RUN.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> KsmStats {
+    KSM.lock().stats()
+}
+
+/// Scan the next few pages, when it is time
+pub fn poll() {
+    if !running() {
+        return;
+    }
+    let now = crate::time::clocksource::now_ns();
+    if now < NEXT_SCAN.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_SCAN.store(now + SLEEP_MS.get().max(1) as u64 * 1_000_000, Ordering::Relaxed);
+
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { super::demand_paging::active_mapper() };
+        KSM.lock().scan(manager, PAGES_TO_SCAN.get().max(1) as usize, &mut mapper);
+    }
+}
+
+fn proc_ksm() -> String {
+    let stats = stats();
+    format!(
+        "run {}\npages_shared {}\npages_sharing {}\npages_unshared {}\npages_volatile {}\nfull_scans {}\n",
+        running() as u8,
+        stats.pages_shared,
+        stats.pages_sharing,
+        stats.pages_unshared,
+        stats.pages_volatile,
+        stats.full_scans,
+    )
+}
+
+pub fn init() {
+    set_running(ENABLED.get());
+    crate::fs::procfs::register("mm/ksm", proc_ksm);
+    if running() {
+        crate::serial_println!("ksm: merging identical pages");
+    }
+}
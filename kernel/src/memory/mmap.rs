@@ -0,0 +1,328 @@
+//! Memory mappings
+//!
+//! mmap() and Win32's MapViewOfFile both come here. A mapping is a region
+//! of the process's address space whose pages demand paging fills on first
+//! touch: from the page cache for files and Win32 sections, zeroed for
+//! anonymous memory. Shared mappings map the cache's own frames, so each
+//! sees the others' stores, and those reach the file on msync or when the
+//! cache writes back. Private mappings are copy-on-write, and so is what a
+//! forked child inherits of them.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::demand_paging::{active_mapper, DEMAND_PAGING};
+use super::page_cache::{self, FileKey};
+use super::userspace::{MemoryRegion, MemoryRegionType, USER_SPACE_MANAGER};
+
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+pub const MS_ASYNC: usize = 0x1;
+pub const MS_INVALIDATE: usize = 0x2;
+pub const MS_SYNC: usize = 0x4;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    InvalidArgument,
+    NoMemory,
+    /// The range is not all mapped
+    NotMapped,
+    /// Writing back to the file failed
+    Io,
+}
+
+/// What a mapping shows
+pub enum Backing {
+    Anonymous,
+    /// A page cache object, from a page-aligned offset in bytes
+    Object(FileKey, u64),
+}
+
+fn page_flags(prot: usize) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn page_range(addr: VirtAddr, length: u64) -> Result<Range<VirtAddr>, MapError> {
+    if length == 0 || !addr.is_aligned(PAGE_SIZE) {
+        return Err(MapError::InvalidArgument);
+    }
+    let length = length.checked_add(PAGE_SIZE - 1).ok_or(MapError::InvalidArgument)? & !(PAGE_SIZE - 1);
+    let end = addr.as_u64().checked_add(length).ok_or(MapError::InvalidArgument)?;
+    Ok(addr..VirtAddr::new(end))
+}
+
+/// Map `length` bytes into process `pid`'s address space, at `addr` if it
+/// is free (or whatever is there, with MAP_FIXED) and otherwise wherever
+/// there is room. Returns where the mapping went.
+pub fn map(
+    pid: u32,
+    addr: VirtAddr,
+    length: u64,
+    prot: usize,
+    flags: usize,
+    backing: Backing,
+) -> Result<VirtAddr, MapError> {
+    let shared = flags & MAP_SHARED != 0;
+    if shared == (flags & MAP_PRIVATE != 0) {
+        return Err(MapError::InvalidArgument);
+    }
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed && (addr.as_u64() == 0 || !addr.is_aligned(PAGE_SIZE)) {
+        return Err(MapError::InvalidArgument);
+    }
+    let hint = page_range(addr.align_down(PAGE_SIZE), length)?;
+    let length = hint.end - hint.start;
+
+    let backing = match backing {
+        Backing::Object(_, offset) if offset % PAGE_SIZE != 0 => return Err(MapError::InvalidArgument),
+        // Shared anonymous memory lives in the page cache so that it stays
+        // shared; the object goes with its last mapping
+        Backing::Anonymous if shared => {
+            let key = page_cache::create_anonymous(length);
+            page_cache::release_anonymous(&key);
+            Backing::Object(key, 0)
+        }
+        backing => backing,
+    };
+    let region_type = match backing {
+        Backing::Object(FileKey::Path(_), _) => MemoryRegionType::MappedFile,
+        Backing::Object(FileKey::Anonymous(_), _) => MemoryRegionType::Shared,
+        Backing::Anonymous => MemoryRegionType::Data,
+    };
+    if fixed {
+        unmap(pid, hint.start, length)?;
+    }
+
+    let range = {
+        let mut usm = USER_SPACE_MANAGER.lock();
+        let space = usm.get_address_space_mut(pid as u64).ok_or(MapError::InvalidArgument)?;
+        let region = |start: VirtAddr| {
+            let mut region = MemoryRegion::new(start, start + length, region_type);
+            region.flags = page_flags(prot);
+            region
+        };
+        if hint.start.as_u64() == 0 || space.add_region(region(hint.start)).is_err() {
+            if fixed {
+                return Err(MapError::InvalidArgument);
+            }
+            let start = space.find_free_region(length, PAGE_SIZE).ok_or(MapError::NoMemory)?;
+            space.add_region(region(start)).map_err(|_| MapError::NoMemory)?;
+            start..start + length
+        } else {
+            hint
+        }
+    };
+
+    // With PROT_NONE every touch faults
+    if prot != PROT_NONE {
+        let mut demand_paging = DEMAND_PAGING.lock();
+        let manager = demand_paging.as_mut().ok_or(MapError::NoMemory)?;
+        match backing {
+            Backing::Object(key, offset) => {
+                manager.map_file(range.clone(), key, offset / PAGE_SIZE, shared, page_flags(prot))
+            }
+            Backing::Anonymous => manager.map_anonymous(range.clone(), page_flags(prot)),
+        }
+    }
+    Ok(range.start)
+}
+
+/// Unmap whatever is mapped in the range. Stores to shared pages stay in
+/// the page cache, to be written back.
+pub fn unmap(pid: u32, addr: VirtAddr, length: u64) -> Result<(), MapError> {
+    let range = page_range(addr, length)?;
+    let removed = USER_SPACE_MANAGER
+        .lock()
+        .get_address_space_mut(pid as u64)
+        .ok_or(MapError::InvalidArgument)?
+        .remove_range(range);
+
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        for piece in removed {
+            manager.release_range(pid, piece, &mut mapper);
+        }
+    }
+    Ok(())
+}
+
+/// Pass stores to shared mappings in the range on to the page cache, and
+/// with MS_SYNC write them to their files before returning
+pub fn sync(pid: u32, addr: VirtAddr, length: u64, flags: usize) -> Result<(), MapError> {
+    let range = page_range(addr, length)?;
+    if flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0 || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC {
+        return Err(MapError::InvalidArgument);
+    }
+
+    let covered: u64 = USER_SPACE_MANAGER
+        .lock()
+        .get_address_space(pid as u64)
+        .ok_or(MapError::InvalidArgument)?
+        .regions
+        .values()
+        .filter(|region| region.start < range.end && region.end > range.start)
+        .map(|region| region.end.min(range.end) - region.start.max(range.start))
+        .sum();
+    if covered < range.end - range.start {
+        return Err(MapError::NotMapped);
+    }
+
+    let keys = {
+        let mut demand_paging = DEMAND_PAGING.lock();
+        let Some(ref mut manager) = *demand_paging else {
+            return Ok(());
+        };
+        let mut mapper = unsafe { active_mapper() };
+        manager.sync_range(range, &mut mapper)
+    };
+    if flags & MS_SYNC != 0 {
+        for key in keys {
+            page_cache::write_back(&key).map_err(|_| MapError::Io)?;
+        }
+    }
+    Ok(())
+}
+
+/// Give process `child` a copy of `parent`'s memory, copy-on-write: its
+/// mappings, which stay shared if they were, and the pages in `ranges`
+pub fn fork(parent: u32, child: u32, ranges: &[Range<VirtAddr>]) -> Result<(), MapError> {
+    let mut ranges = ranges.to_vec();
+    {
+        let mut usm = USER_SPACE_MANAGER.lock();
+        if usm.fork_address_space(parent as u64, child as u64) {
+            let space = usm.get_address_space(child as u64).ok_or(MapError::InvalidArgument)?;
+            ranges.extend(space.regions.values().map(|region| region.start..region.end));
+        }
+    }
+
+    let mut demand_paging = DEMAND_PAGING.lock();
+    let forked = match *demand_paging {
+        Some(ref mut manager) => {
+            let mut mapper = unsafe { active_mapper() };
+            manager.fork(parent, child, &ranges, &mut mapper).map_err(|_| MapError::NoMemory)
+        }
+        None => Ok(()),
+    };
+    if forked.is_err() {
+        USER_SPACE_MANAGER.lock().destroy_address_space(child as u64);
+    }
+    forked
+}
+
+// Regions map() made; the rest of the address space is set up elsewhere
+fn is_mapping(region: &MemoryRegion) -> bool {
+    matches!(region.region_type, MemoryRegionType::MappedFile | MemoryRegionType::Shared | MemoryRegionType::Data)
+}
+
+/// The mapping that `addr` is in
+pub fn mapping_at(pid: u32, addr: VirtAddr) -> Option<Range<VirtAddr>> {
+    let usm = USER_SPACE_MANAGER.lock();
+    let region = usm.get_address_space(pid as u64)?.find_region(addr).filter(|region| is_mapping(region))?;
+    Some(region.start..region.end)
+}
+
+// The stack regions of process `pid`'s address space, and their flags
+fn stacks(pid: u32) -> Vec<(Range<VirtAddr>, PageTableFlags)> {
+    USER_SPACE_MANAGER
+        .lock()
+        .get_address_space(pid as u64)
+        .map(|space| {
+            space
+                .regions
+                .values()
+                .filter(|region| region.region_type == MemoryRegionType::Stack)
+                .map(|region| (region.start..region.end, region.flags))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Give a new process `pid` its address space, with its stack filled on
+/// first touch. Returns the top of the stack.
+pub fn create_process(pid: u32) -> VirtAddr {
+    let (page_table, _) = x86_64::registers::control::Cr3::read();
+    let stack_top = USER_SPACE_MANAGER.lock().create_address_space(pid as u64, page_table).stack_top;
+    let stacks = stacks(pid);
+    if let Some(ref mut manager) = *DEMAND_PAGING.lock() {
+        for (stack, flags) in stacks {
+            manager.map_anonymous(stack, flags);
+        }
+    }
+    stack_top
+}
+
+// Unmap everything the process mapped, and drop the pages it still has
+// where forked processes share addresses
+fn release_mappings(pid: u32) {
+    let mapped: Vec<Range<VirtAddr>> = match USER_SPACE_MANAGER.lock().get_address_space(pid as u64) {
+        Some(space) => space
+            .regions
+            .values()
+            .filter(|region| is_mapping(region))
+            .map(|region| region.start..region.end)
+            .collect(),
+        None => return,
+    };
+    for range in mapped {
+        let _ = unmap(pid, range.start, range.end - range.start);
+    }
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        manager.release_process(pid, &mut mapper);
+    }
+}
+
+/// Drop the memory of an exiting process: its mappings, its stack and its
+/// address space
+pub fn release_process(pid: u32) {
+    release_mappings(pid);
+    let stacks = stacks(pid);
+    USER_SPACE_MANAGER.lock().destroy_address_space(pid as u64);
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        for (stack, _) in stacks {
+            manager.release_range(pid, stack, &mut mapper);
+        }
+    }
+}
+
+/// Drop the memory of a process that is exec'ing: its mappings, and the
+/// pages of its old image in `ranges`. The new image gets a zeroed stack
+/// in the same place.
+pub fn release_image(pid: u32, ranges: &[Range<VirtAddr>]) {
+    release_mappings(pid);
+    let stacks = stacks(pid);
+    let mut demand_paging = DEMAND_PAGING.lock();
+    if let Some(ref mut manager) = *demand_paging {
+        let mut mapper = unsafe { active_mapper() };
+        for range in ranges.iter().filter(|range| range.start < range.end) {
+            manager.release_range(pid, range.clone(), &mut mapper);
+        }
+        for (stack, flags) in stacks {
+            manager.release_range(pid, stack.clone(), &mut mapper);
+            manager.map_anonymous(stack, flags);
+        }
+    }
+}
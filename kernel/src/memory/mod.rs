@@ -9,6 +9,12 @@ pub mod optimized;
 pub mod slab;
 pub mod userspace;
 pub mod protection;
+pub mod zram;
+pub mod ksm;
+pub mod swap;
+pub mod oom;
+pub mod page_cache;
+pub mod mmap;
 
 use x86_64::{
     structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB},
@@ -108,6 +114,56 @@ impl MemoryManager {
     }
 }
 
+/// /proc/meminfo: physical memory, the page cache, swap, zram and the
+/// kernel heap, in kB as on Linux
+pub fn proc_meminfo() -> alloc::string::String {
+    let (total, free, _) = frame_allocator::memory_stats();
+    let cache = page_cache::stats();
+    let (swap_total, swap_used) = swap::totals();
+    let zram = zram::ZRAM.lock().stats();
+    let heap = crate::allocator::memory_stats();
+    let fields = [
+        ("MemTotal", total as u64 * 4),
+        ("MemFree", free as u64 * 4),
+        // Clean cached pages can be dropped at once
+        ("MemAvailable", (free + cache.pages - cache.dirty) as u64 * 4),
+        ("Cached", cache.pages as u64 * 4),
+        ("Dirty", cache.dirty as u64 * 4),
+        ("SwapTotal", swap_total * 4),
+        ("SwapFree", (swap_total - swap_used) * 4),
+        ("Zram", zram.stored_pages as u64 * 4),
+        ("ZramCompressed", zram.compressed_bytes as u64 / 1024),
+        ("HeapTotal", heap.heap_size as u64 / 1024),
+        ("HeapUsed", heap.current_allocated as u64 / 1024),
+        ("HeapPeak", heap.peak_allocated as u64 / 1024),
+        ("Slab", heap.slab_allocated as u64 / 1024),
+    ];
+    fields
+        .iter()
+        .map(|(name, kb)| alloc::format!("{:<16}{:>8} kB\n", alloc::format!("{}:", name), kb))
+        .collect()
+}
+
+/// Fill the frame allocator from the loader's memory map and set up the
+/// page table mapper. Runs after the heap, which the allocator's bitmap
+/// lives on.
+pub fn init(boot_info: Option<&crate::boot::BootInfo>) {
+    match boot_info {
+        Some(info) => frame_allocator::init_frame_allocator(&frame_allocator::usable_regions(info)),
+        None => crate::serial_println!("[MEM] No memory map from the loader; no physical frames to allocate"),
+    }
+    unsafe { protection::init(VirtAddr::new(PHYS_MEM_OFFSET)) };
+    crate::fs::procfs::register("meminfo", proc_meminfo);
+}
+
+/// Background memory work, from the main loop: same-page merging, reclaim
+/// when free memory runs low, and writing dirty file pages back
+pub fn poll() {
+    ksm::poll();
+    demand_paging::poll();
+    page_cache::poll();
+}
+
 #[repr(u32)]
 #[derive(Debug, Clone, Copy)]
 pub enum FreeType {
@@ -0,0 +1,106 @@
+//! The out-of-memory killer
+//!
+//! When a fault needs a frame and reclaim cannot free one, the process with
+//! the highest badness is killed and its pages dropped. As on Linux,
+//! badness is the pages a process has, resident or compressed or swapped
+//! out, plus its `oom_score_adj` in thousandths of all memory and swap.
+//! An adjustment of -1000 exempts a process, as do PIDs 0 and 1.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::Translate, Mapper, Size4KiB};
+
+use super::demand_paging::{DemandPagingManager, DEMAND_PAGING};
+use crate::process::executor::{ProcessExecutor, EXECUTOR};
+
+pub const OOM_SCORE_ADJ_MIN: i16 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i16 = 1000;
+
+// As if by SIGKILL
+const EXIT_KILLED: i32 = -9;
+
+static KILLS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct OomScore {
+    pub pid: u32,
+    pub name: String,
+    pub pages: usize,
+    pub adj: i16,
+    /// None when the process may not be killed
+    pub points: Option<i64>,
+}
+
+fn scores(manager: &DemandPagingManager, executor: &ProcessExecutor) -> Vec<OomScore> {
+    let (frames, _, _) = super::frame_allocator::memory_stats();
+    let total = frames as i64 + super::swap::totals().0 as i64;
+    executor
+        .processes()
+        .map(|pcb| {
+            let pages = pcb.address_space.ranges()
+                .into_iter()
+                .map(|range| {
+                    let (resident, evicted) = manager.usage(range);
+                    resident + evicted
+                })
+                .sum();
+            let exempt = pcb.pid <= 1 || pcb.oom_score_adj <= OOM_SCORE_ADJ_MIN;
+            let points = (pages as i64 + pcb.oom_score_adj as i64 * total / 1000).max(1);
+            OomScore { pid: pcb.pid, name: pcb.name.clone(), pages, adj: pcb.oom_score_adj, points: (!exempt).then_some(points) }
+        })
+        .collect()
+}
+
+/// Kill the process with the most to lose and free its pages. False when
+/// there is none to kill.
+pub fn kill(manager: &mut DemandPagingManager, mapper: &mut (impl Mapper<Size4KiB> + Translate)) -> bool {
+    // A fault inside the executor cannot wait for it
+    let Some(mut executor) = EXECUTOR.try_lock() else {
+        crate::pr_err!("Out of memory, and the process table is busy");
+        return false;
+    };
+    let Some(victim) = scores(manager, &executor)
+        .into_iter()
+        .filter(|score| score.pages > 0 && score.points.is_some())
+        .max_by_key(|score| score.points)
+    else {
+        crate::pr_err!("Out of memory, and no process to kill");
+        return false;
+    };
+
+    let freed: usize = executor
+        .get_process(victim.pid)
+        .map(|pcb| pcb.address_space.ranges())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|range| manager.release_range(victim.pid, range, mapper))
+        .sum();
+    crate::pr_err!(
+        "Out of memory: killed process {} ({}), score {}, {} pages, {} frames freed",
+        victim.pid,
+        victim.name,
+        victim.points.unwrap_or(0),
+        victim.pages,
+        freed
+    );
+    executor.terminate_process(victim.pid, EXIT_KILLED);
+    KILLS.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// Every process's badness, highest first
+pub fn report() -> Vec<OomScore> {
+    let demand_paging = DEMAND_PAGING.lock();
+    let Some(ref manager) = *demand_paging else {
+        return Vec::new();
+    };
+    let mut scores = scores(manager, &EXECUTOR.lock());
+    scores.sort_by_key(|score| core::cmp::Reverse(score.points));
+    scores
+}
+
+/// Processes killed since boot
+pub fn kills() -> u64 {
+    KILLS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,372 @@
+//! The page cache
+//!
+//! File pages in memory, shared by every mapping of a file and by read()
+//! and write() on it. A page is read in on first use and stays until
+//! reclaim needs its frame and nothing maps it. Dirty pages are written
+//! back on msync, before their frames are reclaimed, and every
+//! `vm.dirty_writeback_ms` from the main loop. A page is filled by reading
+//! just its range of the file, so a file of any size can be read a page at
+//! a time. The VFS writes whole files, so writing back rewrites the file
+//! with all of its dirty pages at once.
+//!
+//! Anonymous objects, the Win32 sections backed by the paging file, live
+//! here as well. They start zeroed, have nowhere to be written back to, and
+//! go once their last handle is closed and nothing maps them.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use super::demand_paging::frame_ptr;
+use crate::boot::cmdline::Param;
+use crate::fs::vfs::VFS;
+use crate::fs::FileSystemError;
+
+const PAGE_SIZE: u64 = 4096;
+
+static WRITEBACK_MS: Param<i64> =
+    Param::new("vm.dirty_writeback_ms", 5000, "Milliseconds between write-backs of dirty file pages; 0 turns them off");
+
+static NEXT_WRITEBACK: AtomicU64 = AtomicU64::new(0);
+
+/// What a cached page belongs to
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileKey {
+    /// A file, by its global path
+    Path(String),
+    Anonymous(u64),
+}
+
+struct CachedPage {
+    frame: PhysFrame,
+    dirty: bool,
+}
+
+struct CachedFile {
+    size: u64,
+    pages: BTreeMap<u64, CachedPage>,
+    // An anonymous object whose last handle is closed
+    orphaned: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageCacheStats {
+    pub files: usize,
+    pub pages: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages written back since boot
+    pub written: u64,
+}
+
+struct PageCache {
+    files: BTreeMap<FileKey, CachedFile>,
+    next_anonymous: u64,
+    hits: u64,
+    misses: u64,
+    written: u64,
+}
+
+static CACHE: Mutex<PageCache> = Mutex::new(PageCache {
+    files: BTreeMap::new(),
+    next_anonymous: 1,
+    hits: 0,
+    misses: 0,
+    written: 0,
+});
+
+fn page_mut(frame: PhysFrame) -> &'static mut [u8; PAGE_SIZE as usize] {
+    unsafe { &mut *(frame_ptr(frame) as *mut [u8; PAGE_SIZE as usize]) }
+}
+
+impl PageCache {
+    // A file's entry, asking the VFS for its size the first time
+    fn file(&mut self, key: &FileKey) -> Result<&mut CachedFile, FileSystemError> {
+        if !self.files.contains_key(key) {
+            let FileKey::Path(path) = key else {
+                return Err(FileSystemError::NotFound);
+            };
+            let size = VFS.lock().file_size_unchecked(path)?;
+            self.files.insert(key.clone(), CachedFile { size, pages: BTreeMap::new(), orphaned: false });
+        }
+        Ok(self.files.get_mut(key).unwrap())
+    }
+
+    // Cache `frame` as page `index`, filled from the file. If the page
+    // turned up meanwhile, `frame` is freed and the cached one returned.
+    fn fill(&mut self, key: &FileKey, index: u64, frame: PhysFrame) -> Result<PhysFrame, FileSystemError> {
+        let file = match self.file(key) {
+            Ok(file) => file,
+            Err(e) => {
+                super::frame_allocator::deallocate_frame(frame);
+                return Err(e);
+            }
+        };
+        if let Some(cached) = file.pages.get(&index) {
+            super::frame_allocator::deallocate_frame(frame);
+            return Ok(cached.frame);
+        }
+
+        let data = page_mut(frame);
+        data.fill(0);
+        if let FileKey::Path(path) = key {
+            // Past the end of the file, or of what has been written back
+            // of it, the page reads as zeroes
+            if let Err(e) = VFS.lock().read_at_unchecked(path, index * PAGE_SIZE, data) {
+                super::frame_allocator::deallocate_frame(frame);
+                return Err(e);
+            }
+        }
+        file.pages.insert(index, CachedPage { frame, dirty: false });
+        self.misses += 1;
+        Ok(frame)
+    }
+
+    // A page's frame, read in if need be with a frame straight from the
+    // allocator
+    fn page(&mut self, key: &FileKey, index: u64) -> Result<PhysFrame, FileSystemError> {
+        if let Some(frame) = self.file(key)?.pages.get(&index).map(|cached| cached.frame) {
+            self.hits += 1;
+            return Ok(frame);
+        }
+        let frame = super::frame_allocator::allocate_frame()
+            .ok_or(FileSystemError::IoError(String::from("Out of memory")))?;
+        self.fill(key, index, frame)
+    }
+
+    // Write a file's dirty pages to it; returns how many went
+    fn write_back(&mut self, key: &FileKey) -> Result<usize, FileSystemError> {
+        let FileKey::Path(path) = key else {
+            return Ok(0);
+        };
+        let Some(file) = self.files.get_mut(key) else {
+            return Ok(0);
+        };
+        let dirty: Vec<u64> = file.pages.iter().filter(|(_, page)| page.dirty).map(|(index, _)| *index).collect();
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+
+        let mut vfs = VFS.lock();
+        let mut contents = vfs.read_file_unchecked(path)?;
+        contents.resize(file.size as usize, 0);
+        for index in &dirty {
+            let start = ((index * PAGE_SIZE) as usize).min(contents.len());
+            let end = (start + PAGE_SIZE as usize).min(contents.len());
+            contents[start..end].copy_from_slice(&page_mut(file.pages[index].frame)[..end - start]);
+        }
+        vfs.write_file_unchecked(path, &contents)?;
+        drop(vfs);
+
+        for index in &dirty {
+            if let Some(page) = file.pages.get_mut(index) {
+                page.dirty = false;
+            }
+        }
+        self.written += dirty.len() as u64;
+        Ok(dirty.len())
+    }
+
+    fn write_back_all(&mut self) {
+        let keys: Vec<FileKey> = self.files.keys().cloned().collect();
+        for key in keys {
+            if let Err(e) = self.write_back(&key) {
+                crate::pr_warn!("page cache: writing back {:?} failed: {:?}", key, e);
+            }
+        }
+    }
+}
+
+/// Attach to a file for a handle or a mapping; returns its size
+pub fn open(path: &str) -> Result<u64, FileSystemError> {
+    Ok(CACHE.lock().file(&FileKey::Path(String::from(path)))?.size)
+}
+
+/// A new zeroed object of `size` bytes with no file behind it
+pub fn create_anonymous(size: u64) -> FileKey {
+    let mut cache = CACHE.lock();
+    let key = FileKey::Anonymous(cache.next_anonymous);
+    cache.next_anonymous += 1;
+    cache.files.insert(key.clone(), CachedFile { size, pages: BTreeMap::new(), orphaned: false });
+    key
+}
+
+/// The last handle on an anonymous object is closed; it goes once nothing
+/// maps it either
+pub fn release_anonymous(key: &FileKey) {
+    if let Some(file) = CACHE.lock().files.get_mut(key) {
+        file.orphaned = true;
+    }
+}
+
+pub fn size(key: &FileKey) -> Option<u64> {
+    CACHE.lock().files.get(key).map(|file| file.size)
+}
+
+/// Grow a file to at least `size` bytes; the new part reads as zeroes
+pub fn extend(key: &FileKey, size: u64) -> Result<(), FileSystemError> {
+    let mut cache = CACHE.lock();
+    let file = cache.file(key)?;
+    file.size = file.size.max(size);
+    Ok(())
+}
+
+/// A cached page's frame, if the page is in memory
+pub fn lookup(key: &FileKey, index: u64) -> Option<PhysFrame> {
+    let mut cache = CACHE.lock();
+    let frame = cache.files.get(key)?.pages.get(&index)?.frame;
+    cache.hits += 1;
+    Some(frame)
+}
+
+/// Read page `index` into `frame`, which the cache takes over, and return
+/// the frame that now holds it. The frame is freed on failure.
+pub fn fill(key: &FileKey, index: u64, frame: PhysFrame) -> Result<PhysFrame, FileSystemError> {
+    CACHE.lock().fill(key, index, frame)
+}
+
+/// A page was written through a mapping
+pub fn mark_dirty(key: &FileKey, index: u64) {
+    if let Some(page) = CACHE.lock().files.get_mut(key).and_then(|file| file.pages.get_mut(&index)) {
+        page.dirty = true;
+    }
+}
+
+/// Copy from `offset` into `buffer`, stopping at the end of the file
+pub fn read(key: &FileKey, offset: u64, buffer: &mut [u8]) -> Result<usize, FileSystemError> {
+    let mut cache = CACHE.lock();
+    let size = cache.file(key)?.size;
+    let end = size.min(offset.saturating_add(buffer.len() as u64));
+    let mut at = offset;
+    while at < end {
+        let index = at / PAGE_SIZE;
+        let within = (at % PAGE_SIZE) as usize;
+        let count = (PAGE_SIZE as usize - within).min((end - at) as usize);
+        let frame = cache.page(key, index)?;
+        let done = (at - offset) as usize;
+        buffer[done..done + count].copy_from_slice(&page_mut(frame)[within..within + count]);
+        at += count as u64;
+    }
+    Ok(end.saturating_sub(offset) as usize)
+}
+
+/// Copy `data` in at `offset`, growing the file if it runs past the end.
+/// The pages are written back later.
+pub fn write(key: &FileKey, offset: u64, data: &[u8]) -> Result<usize, FileSystemError> {
+    let mut cache = CACHE.lock();
+    let end = offset + data.len() as u64;
+    let mut at = offset;
+    while at < end {
+        let index = at / PAGE_SIZE;
+        let within = (at % PAGE_SIZE) as usize;
+        let count = (PAGE_SIZE as usize - within).min((end - at) as usize);
+        let frame = cache.page(key, index)?;
+        let done = (at - offset) as usize;
+        page_mut(frame)[within..within + count].copy_from_slice(&data[done..done + count]);
+        if let Some(page) = cache.files.get_mut(key).and_then(|file| file.pages.get_mut(&index)) {
+            page.dirty = true;
+        }
+        at += count as u64;
+    }
+    let file = cache.file(key)?;
+    file.size = file.size.max(end);
+    Ok(data.len())
+}
+
+/// Write a file's dirty pages back to it; returns how many went
+pub fn write_back(key: &FileKey) -> Result<usize, FileSystemError> {
+    CACHE.lock().write_back(key)
+}
+
+/// Free up to `count` frames of pages nothing maps, writing dirty ones back
+/// first. Anonymous pages are only freed with their object. Returns the
+/// frames freed.
+pub fn shrink(count: usize, frames_in_use: &BTreeSet<PhysFrame>, keys_in_use: &BTreeSet<FileKey>) -> usize {
+    let mut cache = CACHE.lock();
+    let mut freed = 0;
+    let keys: Vec<FileKey> = cache.files.keys().cloned().collect();
+    for key in keys {
+        if freed >= count {
+            break;
+        }
+        let anonymous = matches!(key, FileKey::Anonymous(_));
+        if anonymous && (!cache.files[&key].orphaned || keys_in_use.contains(&key)) {
+            continue;
+        }
+        if let Err(e) = cache.write_back(&key) {
+            crate::pr_warn!("page cache: writing back {:?} failed: {:?}", key, e);
+            continue;
+        }
+        let file = cache.files.get_mut(&key).unwrap();
+        let unused: Vec<u64> = file
+            .pages
+            .iter()
+            .filter(|(_, page)| (anonymous || !page.dirty) && !frames_in_use.contains(&page.frame))
+            .map(|(index, _)| *index)
+            .take(if anonymous { usize::MAX } else { count - freed })
+            .collect();
+        for index in unused {
+            if let Some(page) = file.pages.remove(&index) {
+                super::frame_allocator::deallocate_frame(page.frame);
+                freed += 1;
+            }
+        }
+        // Read again from the file should it be wanted later
+        if file.pages.is_empty() {
+            cache.files.remove(&key);
+        }
+    }
+    freed
+}
+
+pub fn stats() -> PageCacheStats {
+    let cache = CACHE.lock();
+    let pages = cache.files.values().map(|file| file.pages.len()).sum();
+    let dirty = cache.files.values().flat_map(|file| file.pages.values()).filter(|page| page.dirty).count();
+    PageCacheStats {
+        files: cache.files.len(),
+        pages,
+        dirty,
+        hits: cache.hits,
+        misses: cache.misses,
+        written: cache.written,
+    }
+}
+
+/// Write dirty pages back, when it is time
+pub fn poll() {
+    let interval = WRITEBACK_MS.get();
+    if interval <= 0 {
+        return;
+    }
+    let now = crate::time::clocksource::now_ns();
+    if now < NEXT_WRITEBACK.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_WRITEBACK.store(now + interval as u64 * 1_000_000, Ordering::Relaxed);
+    sync();
+}
+
+/// Write every dirty page back now
+pub fn sync() {
+    // Stores through shared mappings are only in the page tables so far
+    super::demand_paging::collect_dirty();
+    CACHE.lock().write_back_all();
+}
+
+fn proc_page_cache() -> String {
+    let stats = stats();
+    format!(
+        "files {}\npages {}\ndirty {}\nhits {}\nmisses {}\nwritten {}\n",
+        stats.files, stats.pages, stats.dirty, stats.hits, stats.misses, stats.written,
+    )
+}
+
+pub fn init() {
+    crate::fs::procfs::register("mm/page_cache", proc_page_cache);
+}
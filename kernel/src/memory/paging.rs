@@ -111,6 +111,37 @@ pub fn translate_addr(
     })
 }
 
+/// Translate an address through the active page tables without a mapper,
+/// reaching the tables through the identity mapping of physical memory.
+/// The flags are those of the leaf entry, with WRITABLE and USER_ACCESSIBLE
+/// kept only if every level grants them.
+pub fn translate_current(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    use x86_64::registers::control::Cr3;
+
+    let address = addr.as_u64();
+    let mut table = Cr3::read().0.start_address().as_u64();
+    let mut access = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for level in (1..=4u64).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let index = (address >> shift) & 0x1ff;
+        let entry = unsafe { core::ptr::read_volatile((table + index * 8) as *const u64) };
+        let flags = PageTableFlags::from_bits_truncate(entry);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        access &= flags;
+        let frame = entry & 0x000f_ffff_ffff_f000;
+        // 1 GiB and 2 MiB pages
+        if level == 1 || ((level == 3 || level == 2) && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            let offset_mask = (1u64 << shift) - 1;
+            let flags = (flags - PageTableFlags::WRITABLE - PageTableFlags::USER_ACCESSIBLE) | access;
+            return Some((PhysAddr::new((frame & !offset_mask) | (address & offset_mask)), flags));
+        }
+        table = frame;
+    }
+    None
+}
+
 /// Create a new mapping for a given virtual address range
 pub fn create_mapping(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -7,25 +7,23 @@ use spin::Mutex;
 static mut MAPPER: Option<OffsetPageTable<'static>> = None;
 static mut FRAME_ALLOCATOR: Option<BootInfoFrameAllocator> = None;
 
-pub struct BootInfoFrameAllocator {
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    pub unsafe fn init() -> Self {
-        Self { next: 0 }
-    }
-}
+/// Hands out frames from the global bitmap allocator, which is filled from
+/// the loader's memory map
+pub struct BootInfoFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        // Simplified frame allocation
-        let frame = PhysFrame::containing_address(x86_64::PhysAddr::new((self.next * 0x1000) as u64));
-        self.next += 1;
-        Some(frame)
+        super::frame_allocator::allocate_frame()
     }
 }
 
+/// Set up the page table mapper over the direct map, once the frame
+/// allocator has been filled
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    MAPPER = Some(super::paging::init(physical_memory_offset));
+    FRAME_ALLOCATOR = Some(BootInfoFrameAllocator);
+}
+
 pub unsafe fn get_mapper() -> &'static mut OffsetPageTable<'static> {
     MAPPER.as_mut().expect("Mapper not initialized")
 }
@@ -16,27 +16,53 @@ struct SlabHeader {
     bitmap: u64,
 }
 
+// Where a slab's objects live
+enum SlabMemory {
+    Heap(Vec<u8>),
+    // Contiguous frames on the slab's node, through the physical map
+    Frames(NonNull<u8>),
+}
+
 struct Slab {
     header: SlabHeader,
-    data: Vec<u8>,
+    data: SlabMemory,
+    len: usize,
+    node: Option<u32>,
 }
 
 impl Slab {
+    fn header(object_size: usize) -> SlabHeader {
+        SlabHeader {
+            size: object_size,
+            free_count: OBJECTS_PER_SLAB,
+            next_free: Some(0),
+            bitmap: !0u64,
+        }
+    }
+
     fn new(object_size: usize) -> Self {
         let total_size = object_size * OBJECTS_PER_SLAB;
-        let mut slab = Slab {
-            header: SlabHeader {
-                size: object_size,
-                free_count: OBJECTS_PER_SLAB,
-                next_free: Some(0),
-                bitmap: !0u64,
-            },
-            data: Vec::with_capacity(total_size),
-        };
+        let mut data = Vec::with_capacity(total_size);
         unsafe {
-            slab.data.set_len(total_size);
+            data.set_len(total_size);
+        }
+        Slab { header: Self::header(object_size), data: SlabMemory::Heap(data), len: total_size, node: None }
+    }
+
+    // A slab in frames on `node`, if it has room for one
+    fn on_node(object_size: usize, node: u32) -> Option<Self> {
+        let total_size = object_size * OBJECTS_PER_SLAB;
+        let frames = (total_size + 4095) / 4096;
+        let frame = super::frame_allocator::FRAME_ALLOCATOR.lock().allocate_contiguous(frames, Some(node))?;
+        let ptr = NonNull::new((super::PHYS_MEM_OFFSET + frame.start_address().as_u64()) as *mut u8)?;
+        Some(Slab { header: Self::header(object_size), data: SlabMemory::Frames(ptr), len: total_size, node: Some(node) })
+    }
+
+    fn base(&mut self) -> *mut u8 {
+        match &mut self.data {
+            SlabMemory::Heap(data) => data.as_mut_ptr(),
+            SlabMemory::Frames(ptr) => ptr.as_ptr(),
         }
-        slab
     }
 
     fn allocate(&mut self) -> Option<NonNull<u8>> {
@@ -61,7 +87,7 @@ impl Slab {
             }
 
             let offset = index * self.header.size;
-            let ptr = unsafe { self.data.as_mut_ptr().add(offset) };
+            let ptr = unsafe { self.base().add(offset) };
             NonNull::new(ptr)
         } else {
             None
@@ -69,11 +95,10 @@ impl Slab {
     }
 
     fn deallocate(&mut self, ptr: NonNull<u8>) -> bool {
-        let base = self.data.as_ptr();
         let addr = ptr.as_ptr() as usize;
-        let base_addr = base as usize;
+        let base_addr = self.base() as usize;
 
-        if addr < base_addr || addr >= base_addr + self.data.len() {
+        if addr < base_addr || addr >= base_addr + self.len {
             return false;
         }
 
@@ -127,20 +152,37 @@ impl SlabCache {
         }
     }
 
-    fn allocate(&mut self) -> Option<NonNull<u8>> {
-        for &idx in &self.partial_slabs {
-            if let Some(ptr) = self.slabs[idx].allocate() {
-                if self.slabs[idx].is_full() {
-                    self.partial_slabs.retain(|&x| x != idx);
-                    self.full_slabs.push(idx);
-                }
-                return Some(ptr);
-            }
+    // From a slab on `node` if one has room or a new one can be made
+    // there, else from any slab
+    fn allocate(&mut self, node: Option<u32>) -> Option<NonNull<u8>> {
+        if let Some(ptr) = self.allocate_partial(|slab| node.is_none() || slab.node == node) {
+            return Some(ptr);
+        }
+
+        if let Some(slab) = node.and_then(|node| Slab::on_node(self.object_size, node)) {
+            return self.allocate_new(slab);
+        }
+
+        if let Some(ptr) = self.allocate_partial(|_| true) {
+            return Some(ptr);
+        }
+
+        self.allocate_new(Slab::new(self.object_size))
+    }
+
+    fn allocate_partial(&mut self, usable: impl Fn(&Slab) -> bool) -> Option<NonNull<u8>> {
+        let idx = self.partial_slabs.iter().copied().find(|&idx| usable(&self.slabs[idx]))?;
+        let ptr = self.slabs[idx].allocate()?;
+        if self.slabs[idx].is_full() {
+            self.partial_slabs.retain(|&x| x != idx);
+            self.full_slabs.push(idx);
         }
+        Some(ptr)
+    }
 
-        let new_slab = Slab::new(self.object_size);
+    fn allocate_new(&mut self, slab: Slab) -> Option<NonNull<u8>> {
         let idx = self.slabs.len();
-        self.slabs.push(new_slab);
+        self.slabs.push(slab);
         self.partial_slabs.push(idx);
 
         self.slabs[idx].allocate()
@@ -187,6 +229,15 @@ impl SlabAllocator {
         }
     }
 
+    // The node to allocate on, or None to let slabs come from the heap
+    fn node() -> Option<u32> {
+        if crate::numa::NUMA_TOPOLOGY.node_count() <= 1 {
+            return None;
+        }
+        let policy = super::frame_allocator::FRAME_ALLOCATOR.lock().policy();
+        Some(policy.placement().node)
+    }
+
     fn get_slab_index(size: usize) -> Option<usize> {
         SLAB_SIZES.iter().position(|&s| s >= size)
     }
@@ -201,7 +252,7 @@ impl SlabAllocator {
 
         if let Some(idx) = Self::get_slab_index(size) {
             if idx < self.caches.len() {
-                return self.caches[idx].allocate();
+                return self.caches[idx].allocate(Self::node());
             }
         }
 
@@ -0,0 +1,403 @@
+//! Swap areas on disk
+//!
+//! An area is a whole disk (`diskN`), an MBR partition (`diskNpM`), or a
+//! file on a mounted file system (`/swapfile`), in the Linux swap format:
+//! page 0 is a header ending in `SWAPSPACE2`, naming the last usable page
+//! and any bad ones. `mkswap` writes that header. A file is reached through
+//! the sectors its file system gives it when it is turned on, so it must
+//! already have its full size and must not move while in use.
+//!
+//! Pages go to the first area with room, in the order the areas were
+//! turned on. `swap=` lists areas to turn on at boot.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+use crate::drivers::block;
+use crate::drivers::disk::{DISK_MANAGER, SECTOR_SIZE};
+
+const PAGE_SIZE: usize = 4096;
+const SECTORS_PER_PAGE: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+// The header, as Linux's union swap_header lays it out
+const MAGIC: &[u8; 10] = b"SWAPSPACE2";
+const MAGIC_OFFSET: usize = PAGE_SIZE - 10;
+const VERSION: usize = 1024;
+const LAST_PAGE: usize = 1028;
+const NR_BADPAGES: usize = 1032;
+const UUID: usize = 1036;
+const BADPAGES: usize = 1536;
+const MAX_BADPAGES: usize = (MAGIC_OFFSET - BADPAGES) / 4;
+
+// Too small to be worth the header
+const MIN_PAGES: u64 = 10;
+
+static AREAS: Param<&str> =
+    Param::new("swap", "", "Swap areas to turn on at boot, comma separated: diskN, diskNpM or a file path");
+
+struct SwapArea {
+    name: String,
+    is_file: bool,
+    disk: usize,
+    // Runs of sectors, as (first, count), holding the area in order
+    extents: Vec<(u64, u64)>,
+    // Pages, counting the header
+    pages: u32,
+    // A bit per page, set when in use; the header and bad pages stay set
+    used: Vec<u64>,
+    in_use: u32,
+    bad: u32,
+    next: u32,
+    // Being turned off: no new pages go here
+    draining: bool,
+}
+
+/// An area as /proc/swaps shows it
+#[derive(Debug, Clone)]
+pub struct SwapInfo {
+    pub name: String,
+    pub is_file: bool,
+    /// Usable pages, leaving out the header and bad pages
+    pub pages: u32,
+    pub used: u32,
+    pub priority: i32,
+}
+
+impl SwapArea {
+    fn is_used(&self, page: u32) -> bool {
+        self.used[page as usize / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_used(&mut self, page: u32, used: bool) {
+        let bit = 1 << (page % 64);
+        if used {
+            self.used[page as usize / 64] |= bit;
+        } else {
+            self.used[page as usize / 64] &= !bit;
+        }
+    }
+
+    fn take(&mut self) -> Option<u32> {
+        if self.draining || self.in_use + self.bad + 1 >= self.pages {
+            return None;
+        }
+        let page = (self.next..self.pages).chain(1..self.next).find(|&page| !self.is_used(page))?;
+        self.set_used(page, true);
+        self.in_use += 1;
+        self.next = page + 1;
+        Some(page)
+    }
+
+    fn info(&self, index: usize) -> SwapInfo {
+        SwapInfo {
+            name: self.name.clone(),
+            is_file: self.is_file,
+            pages: self.pages - 1 - self.bad,
+            used: self.in_use,
+            priority: -(index as i32) - 2,
+        }
+    }
+}
+
+// Areas keep their index, which is part of each slot, until turned off
+static SWAP: Mutex<Vec<Option<SwapArea>>> = Mutex::new(Vec::new());
+
+static PAGES_IN: AtomicU64 = AtomicU64::new(0);
+static PAGES_OUT: AtomicU64 = AtomicU64::new(0);
+
+fn slot(area: usize, page: u32) -> usize {
+    (area << 32) | page as usize
+}
+
+fn split(slot: usize) -> (usize, u32) {
+    (slot >> 32, slot as u32)
+}
+
+// The runs of `count` sectors from `first` within the extents
+fn runs(extents: &[(u64, u64)], mut first: u64, mut count: u64) -> Vec<(u64, u64)> {
+    let mut runs = Vec::new();
+    for &(start, length) in extents {
+        if count == 0 {
+            break;
+        }
+        if first >= length {
+            first -= length;
+            continue;
+        }
+        let take = (length - first).min(count);
+        runs.push((start + first, take));
+        count -= take;
+        first = 0;
+    }
+    runs
+}
+
+fn transfer(
+    disk: usize,
+    extents: &[(u64, u64)],
+    page: u64,
+    buffer: &mut [u8; PAGE_SIZE],
+    write: bool,
+) -> Result<(), &'static str> {
+    let runs = runs(extents, page * SECTORS_PER_PAGE, SECTORS_PER_PAGE);
+    if runs.iter().map(|(_, count)| count).sum::<u64>() != SECTORS_PER_PAGE {
+        return Err("page beyond the end of the swap area");
+    }
+    let mut offset = 0;
+    for (sector, count) in runs {
+        let bytes = count as usize * SECTOR_SIZE;
+        let chunk = &mut buffer[offset..offset + bytes];
+        let result = if write {
+            block::write(disk, sector, count as u32, chunk)
+        } else {
+            block::read(disk, sector, count as u32, chunk)
+        };
+        result.map_err(|_| "swap I/O error")?;
+        offset += bytes;
+    }
+    Ok(())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// The disk and sectors a target names, and whether it is a file
+fn resolve(target: &str) -> Result<(usize, Vec<(u64, u64)>, bool), &'static str> {
+    if target.starts_with('/') {
+        let (disk, extents) = crate::fs::vfs::VFS
+            .lock()
+            .block_map_unchecked(target)
+            .map_err(|_| "no such file, or its file system cannot hold swap")?;
+        return Ok((disk, extents, true));
+    }
+
+    let spec = target.strip_prefix("disk").ok_or("not diskN, diskNpM or a file path")?;
+    let (disk, partition) = match spec.split_once('p') {
+        Some((disk, partition)) => (disk, Some(partition)),
+        None => (spec, None),
+    };
+    let disk: usize = disk.parse().map_err(|_| "bad disk number")?;
+    let sectors = DISK_MANAGER.lock().get_disk(disk).ok_or("no such disk")?.get_info().sectors;
+    let Some(partition) = partition else {
+        return Ok((disk, alloc::vec![(0, sectors)], false));
+    };
+
+    let partition: usize = partition.parse().map_err(|_| "bad partition number")?;
+    if !(1..=4).contains(&partition) {
+        return Err("only MBR primary partitions 1 to 4");
+    }
+    let mut mbr = [0u8; SECTOR_SIZE];
+    block::read(disk, 0, 1, &mut mbr).map_err(|_| "cannot read the partition table")?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Err("no MBR partition table");
+    }
+    let entry = 446 + (partition - 1) * 16;
+    let (kind, first, count) = (mbr[entry + 4], le32(&mbr, entry + 8), le32(&mbr, entry + 12));
+    if kind == 0 || count == 0 {
+        return Err("no such partition");
+    }
+    Ok((disk, alloc::vec![(first as u64, count as u64)], false))
+}
+
+/// Write a swap header over the start of `target`, giving the pages it
+/// makes usable
+pub fn mkswap(target: &str) -> Result<u32, &'static str> {
+    if SWAP.lock().iter().flatten().any(|area| area.name == target) {
+        return Err("in use");
+    }
+    let (disk, extents, _) = resolve(target)?;
+    let pages = extents.iter().map(|(_, count)| count).sum::<u64>() / SECTORS_PER_PAGE;
+    if pages < MIN_PAGES {
+        return Err("too small for swap");
+    }
+    let pages = pages.min(u32::MAX as u64) as u32;
+
+    let mut header = [0u8; PAGE_SIZE];
+    header[VERSION..VERSION + 4].copy_from_slice(&1u32.to_le_bytes());
+    header[LAST_PAGE..LAST_PAGE + 4].copy_from_slice(&(pages - 1).to_le_bytes());
+    crate::crypto::random_bytes(&mut header[UUID..UUID + 16]);
+    header[MAGIC_OFFSET..].copy_from_slice(MAGIC);
+    transfer(disk, &extents, 0, &mut header, true)?;
+    Ok(pages - 1)
+}
+
+/// Start paging out to `target`, which `mkswap` has prepared
+pub fn swapon(target: &str) -> Result<(), &'static str> {
+    if SWAP.lock().iter().flatten().any(|area| area.name == target) {
+        return Err("already on");
+    }
+    let (disk, extents, is_file) = resolve(target)?;
+    let mut header = [0u8; PAGE_SIZE];
+    transfer(disk, &extents, 0, &mut header, false)?;
+    if &header[MAGIC_OFFSET..] != MAGIC {
+        return Err("no swap signature; run mkswap");
+    }
+    if le32(&header, VERSION) != 1 {
+        return Err("unsupported swap version");
+    }
+
+    // The header may claim more than the device now holds
+    let size = extents.iter().map(|(_, count)| count).sum::<u64>() / SECTORS_PER_PAGE;
+    let pages = (le32(&header, LAST_PAGE) as u64 + 1).min(size) as u32;
+    if (pages as u64) < MIN_PAGES {
+        return Err("too small for swap");
+    }
+    let mut area = SwapArea {
+        name: target.to_string(),
+        is_file,
+        disk,
+        extents,
+        pages,
+        used: alloc::vec![0; (pages as usize).div_ceil(64)],
+        in_use: 0,
+        bad: 0,
+        next: 1,
+        draining: false,
+    };
+    area.set_used(0, true);
+    let bad = (le32(&header, NR_BADPAGES) as usize).min(MAX_BADPAGES);
+    for index in 0..bad {
+        let page = le32(&header, BADPAGES + index * 4);
+        if page > 0 && page < pages && !area.is_used(page) {
+            area.set_used(page, true);
+            area.bad += 1;
+        }
+    }
+
+    let mut areas = SWAP.lock();
+    let info = area.info(0);
+    match areas.iter().position(Option::is_none) {
+        Some(index) => areas[index] = Some(area),
+        None => areas.push(Some(area)),
+    }
+    crate::pr_info!("swap: {} on, {} KB", target, info.pages as u64 * 4);
+    Ok(())
+}
+
+/// Bring every page in `target` back into memory and stop using it
+pub fn swapoff(target: &str) -> Result<(), &'static str> {
+    let index = {
+        let mut areas = SWAP.lock();
+        let index = areas.iter().position(|area| area.as_ref().is_some_and(|area| area.name == target));
+        let index = index.ok_or("not on")?;
+        areas[index].as_mut().unwrap().draining = true;
+        index
+    };
+
+    let result = {
+        let mut demand_paging = super::demand_paging::DEMAND_PAGING.lock();
+        match *demand_paging {
+            Some(ref mut manager) => {
+                let mut mapper = unsafe { super::demand_paging::active_mapper() };
+                manager.swap_in_area(index, &mut mapper)
+            }
+            None => Ok(0),
+        }
+    };
+
+    let mut areas = SWAP.lock();
+    match result {
+        Ok(_) => {
+            areas[index] = None;
+            crate::pr_info!("swap: {} off", target);
+            Ok(())
+        }
+        Err(e) => {
+            areas[index].as_mut().unwrap().draining = false;
+            Err(e)
+        }
+    }
+}
+
+/// Write a page to the first area with room, giving back its slot
+pub fn write_page(data: &[u8; PAGE_SIZE]) -> Option<usize> {
+    let mut areas = SWAP.lock();
+    for (index, area) in areas.iter_mut().enumerate() {
+        let Some(area) = area else { continue };
+        let Some(page) = area.take() else { continue };
+        let mut buffer = *data;
+        if transfer(area.disk, &area.extents, page as u64, &mut buffer, true).is_err() {
+            // Keep it out of use, as if the header had listed it
+            area.in_use -= 1;
+            area.bad += 1;
+            continue;
+        }
+        PAGES_OUT.fetch_add(1, Ordering::Relaxed);
+        return Some(slot(index, page));
+    }
+    None
+}
+
+/// Read a slot back; it stays in use until `free_slot`
+pub fn read_page(slot: usize, data: &mut [u8; PAGE_SIZE]) -> Result<(), &'static str> {
+    let (index, page) = split(slot);
+    let areas = SWAP.lock();
+    let area = areas.get(index).and_then(Option::as_ref).ok_or("swap area gone")?;
+    if page >= area.pages || !area.is_used(page) {
+        return Err("swap slot not in use");
+    }
+    transfer(area.disk, &area.extents, page as u64, data, false)?;
+    PAGES_IN.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn free_slot(slot: usize) {
+    let (index, page) = split(slot);
+    let mut areas = SWAP.lock();
+    if let Some(Some(area)) = areas.get_mut(index) {
+        if page > 0 && page < area.pages && area.is_used(page) {
+            area.set_used(page, false);
+            area.in_use -= 1;
+        }
+    }
+}
+
+/// The area a slot is in
+pub fn area_of(slot: usize) -> usize {
+    split(slot).0
+}
+
+pub fn areas() -> Vec<SwapInfo> {
+    SWAP.lock().iter().enumerate().filter_map(|(index, area)| Some(area.as_ref()?.info(index))).collect()
+}
+
+/// Usable and used pages over every area
+pub fn totals() -> (u64, u64) {
+    areas().iter().fold((0, 0), |(total, used), area| (total + area.pages as u64, used + area.used as u64))
+}
+
+/// Pages read in and written out since boot
+pub fn counts() -> (u64, u64) {
+    (PAGES_IN.load(Ordering::Relaxed), PAGES_OUT.load(Ordering::Relaxed))
+}
+
+// /proc/swaps, as on Linux
+fn proc_swaps() -> String {
+    let mut text = String::from("Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n");
+    for area in areas() {
+        text += &format!(
+            "{:<40}{:<16}{:<16}{:<16}{}\n",
+            area.name,
+            if area.is_file { "file" } else { "partition" },
+            area.pages as u64 * 4,
+            area.used as u64 * 4,
+            area.priority
+        );
+    }
+    text
+}
+
+/// Turn on the areas `swap=` names; runs once file systems are mounted
+pub fn init() {
+    crate::fs::procfs::register("swaps", proc_swaps);
+    for target in AREAS.get().split(',').filter(|target| !target.is_empty()) {
+        if let Err(e) = swapon(target) {
+            crate::pr_warn!("swap: {}: {}", target, e);
+        }
+    }
+}
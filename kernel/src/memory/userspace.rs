@@ -94,14 +94,22 @@ pub struct AddressSpace {
     pub page_table: PhysFrame,
     pub regions: BTreeMap<u64, MemoryRegion>,
     pub brk: VirtAddr,
+    // Per-process bases, randomized when user ASLR is enabled
+    pub stack_top: VirtAddr,
+    pub heap_start: VirtAddr,
+    pub mmap_base: VirtAddr,
 }
 
 impl AddressSpace {
     pub fn new(page_table: PhysFrame) -> Self {
+        let layout = crate::security::aslr::randomize_layout();
         AddressSpace {
             page_table,
             regions: BTreeMap::new(),
-            brk: VirtAddr::new(USER_HEAP_START),
+            brk: VirtAddr::new(layout.heap_start),
+            stack_top: VirtAddr::new(layout.stack_top),
+            heap_start: VirtAddr::new(layout.heap_start),
+            mmap_base: VirtAddr::new(layout.mmap_base),
         }
     }
 
@@ -124,6 +132,33 @@ impl AddressSpace {
         self.regions.remove(&start.as_u64())
     }
 
+    /// Take `range` out of whatever regions it covers, trimming or splitting
+    /// those that reach past it. Returns the parts removed.
+    pub fn remove_range(&mut self, range: Range<VirtAddr>) -> Vec<Range<VirtAddr>> {
+        let overlapping: Vec<u64> = self.regions
+            .values()
+            .filter(|region| region.start < range.end && region.end > range.start)
+            .map(|region| region.start.as_u64())
+            .collect();
+
+        let mut removed = Vec::new();
+        for start in overlapping {
+            let region = self.regions.remove(&start).unwrap();
+            removed.push(region.start.max(range.start)..region.end.min(range.end));
+            let pieces = [region.start..range.start, range.end..region.end];
+            for piece in pieces.into_iter().filter(|piece| piece.start < piece.end) {
+                self.regions.insert(piece.start.as_u64(), MemoryRegion {
+                    start: piece.start,
+                    end: piece.end,
+                    region_type: region.region_type,
+                    flags: region.flags,
+                    mapped: region.mapped,
+                });
+            }
+        }
+        removed
+    }
+
     pub fn find_region(&self, addr: VirtAddr) -> Option<&MemoryRegion> {
         for (_, region) in &self.regions {
             if region.contains(addr) {
@@ -142,15 +177,25 @@ impl AddressSpace {
         None
     }
 
+    /// Free range for a new mapping: above the mmap base if possible,
+    /// otherwise anywhere in user space
     pub fn find_free_region(&self, size: u64, alignment: u64) -> Option<VirtAddr> {
-        let mut current = VirtAddr::new(USER_SPACE_START);
+        self.find_free_region_from(self.mmap_base, size, alignment)
+            .or_else(|| self.find_free_region_from(VirtAddr::new(USER_SPACE_START), size, alignment))
+    }
+
+    fn find_free_region_from(&self, start: VirtAddr, size: u64, alignment: u64) -> Option<VirtAddr> {
+        let mut current = start;
         let size_aligned = (size + alignment - 1) & !(alignment - 1);
 
         let mut sorted_regions: Vec<_> = self.regions.values().collect();
         sorted_regions.sort_by_key(|r| r.start);
 
         for region in sorted_regions {
-            let gap_end = region.start;
+            if region.end <= current {
+                continue;
+            }
+            let gap_end = region.start.max(current);
             let gap_size = gap_end.as_u64() - current.as_u64();
 
             if gap_size >= size_aligned {
@@ -175,7 +220,7 @@ impl AddressSpace {
     }
 
     pub fn set_brk(&mut self, new_brk: VirtAddr) -> Result<VirtAddr, &'static str> {
-        if new_brk < VirtAddr::new(USER_HEAP_START) || new_brk > VirtAddr::new(USER_HEAP_END) {
+        if new_brk < self.heap_start || new_brk > self.heap_start + USER_HEAP_SIZE {
             return Err("Invalid brk address");
         }
 
@@ -185,51 +230,79 @@ impl AddressSpace {
     }
 }
 
+// Address spaces are keyed by pid
 pub struct UserSpaceManager {
     address_spaces: BTreeMap<u64, AddressSpace>,
-    next_asid: u64,
 }
 
 impl UserSpaceManager {
     pub fn new() -> Self {
         UserSpaceManager {
             address_spaces: BTreeMap::new(),
-            next_asid: 1,
         }
     }
 
-    pub fn create_address_space(&mut self, page_table: PhysFrame) -> u64 {
-        let asid = self.next_asid;
-        self.next_asid += 1;
-
+    /// Give process `pid` a new address space, with a stack and an empty
+    /// heap at its own bases, in place of any it had
+    pub fn create_address_space(&mut self, pid: u64, page_table: PhysFrame) -> &AddressSpace {
         let mut space = AddressSpace::new(page_table);
 
+        let stack_top = space.stack_top;
+        let heap_start = space.heap_start;
         space.add_region(MemoryRegion::new(
-            VirtAddr::new(USER_STACK_BOTTOM),
-            VirtAddr::new(USER_STACK_TOP),
+            stack_top - USER_STACK_SIZE,
+            stack_top,
             MemoryRegionType::Stack,
         )).unwrap();
 
         space.add_region(MemoryRegion::new(
-            VirtAddr::new(USER_HEAP_START),
-            VirtAddr::new(USER_HEAP_START),
+            heap_start,
+            heap_start,
             MemoryRegionType::Heap,
         )).unwrap();
 
-        self.address_spaces.insert(asid, space);
-        asid
+        self.address_spaces.insert(pid, space);
+        &self.address_spaces[&pid]
+    }
+
+    /// Give `child` a copy of `parent`'s layout: its regions, break and
+    /// bases. False if `parent` has no address space.
+    pub fn fork_address_space(&mut self, parent: u64, child: u64) -> bool {
+        let Some(space) = self.address_spaces.get(&parent) else {
+            return false;
+        };
+        let regions = space.regions
+            .iter()
+            .map(|(&start, region)| (start, MemoryRegion {
+                start: region.start,
+                end: region.end,
+                region_type: region.region_type,
+                flags: region.flags,
+                mapped: region.mapped,
+            }))
+            .collect();
+        let copy = AddressSpace {
+            page_table: space.page_table,
+            regions,
+            brk: space.brk,
+            stack_top: space.stack_top,
+            heap_start: space.heap_start,
+            mmap_base: space.mmap_base,
+        };
+        self.address_spaces.insert(child, copy);
+        true
     }
 
-    pub fn destroy_address_space(&mut self, asid: u64) -> Option<AddressSpace> {
-        self.address_spaces.remove(&asid)
+    pub fn destroy_address_space(&mut self, pid: u64) -> Option<AddressSpace> {
+        self.address_spaces.remove(&pid)
     }
 
-    pub fn get_address_space(&self, asid: u64) -> Option<&AddressSpace> {
-        self.address_spaces.get(&asid)
+    pub fn get_address_space(&self, pid: u64) -> Option<&AddressSpace> {
+        self.address_spaces.get(&pid)
     }
 
-    pub fn get_address_space_mut(&mut self, asid: u64) -> Option<&mut AddressSpace> {
-        self.address_spaces.get_mut(&asid)
+    pub fn get_address_space_mut(&mut self, pid: u64) -> Option<&mut AddressSpace> {
+        self.address_spaces.get_mut(&pid)
     }
 }
 
@@ -0,0 +1,443 @@
+//! Compressed swap in RAM
+//!
+//! Pages that demand paging evicts under memory pressure are kept here,
+//! compressed, instead of going to disk. A page filled with one repeated
+//! word is kept as that word alone. Others are compressed in the LZ4 block
+//! format; pages that do not shrink to three quarters of their size are
+//! refused and left for another tier. `zram.size=` sets how many MB of
+//! pages, before compression, the store takes; it is off by default.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+
+const PAGE_SIZE: usize = 4096;
+
+// Larger than this after compression is not worth keeping
+const MAX_COMPRESSED: usize = PAGE_SIZE * 3 / 4;
+
+static SIZE_MB: Param<i64> = Param::new("zram.size", 0, "Compressed swap in RAM, in MB of pages; 0 turns it off");
+
+enum Entry {
+    // Every 64-bit word of the page is this one
+    Same(u64),
+    Compressed(Box<[u8]>),
+}
+
+/// Counts in the form of Linux's zram `mm_stat`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZramStats {
+    pub capacity_pages: usize,
+    pub stored_pages: usize,
+    pub same_pages: usize,
+    pub compressed_bytes: usize,
+    /// Pages that did not compress well enough to keep
+    pub rejected: u64,
+    pub loads: u64,
+}
+
+pub struct Zram {
+    entries: Vec<Option<Entry>>,
+    free: Vec<usize>,
+    stats: ZramStats,
+}
+
+impl Zram {
+    const fn new() -> Self {
+        let stats =
+            ZramStats { capacity_pages: 0, stored_pages: 0, same_pages: 0, compressed_bytes: 0, rejected: 0, loads: 0 };
+        Zram { entries: Vec::new(), free: Vec::new(), stats }
+    }
+
+    /// Keep a page, giving back its handle, or None if the store is full or
+    /// the page does not compress
+    pub fn store(&mut self, page: &[u8; PAGE_SIZE]) -> Option<usize> {
+        if self.stats.stored_pages >= self.stats.capacity_pages {
+            return None;
+        }
+        let first = u64::from_ne_bytes(page[..8].try_into().unwrap());
+        let entry = if page.chunks_exact(8).all(|word| u64::from_ne_bytes(word.try_into().unwrap()) == first) {
+            self.stats.same_pages += 1;
+            Entry::Same(first)
+        } else {
+            let compressed = compress(page);
+            if compressed.len() > MAX_COMPRESSED {
+                self.stats.rejected += 1;
+                return None;
+            }
+            self.stats.compressed_bytes += compressed.len();
+            Entry::Compressed(compressed.into_boxed_slice())
+        };
+        self.stats.stored_pages += 1;
+        let handle = match self.free.pop() {
+            Some(handle) => handle,
+            None => {
+                self.entries.push(None);
+                self.entries.len() - 1
+            }
+        };
+        self.entries[handle] = Some(entry);
+        Some(handle)
+    }
+
+    /// Fill `page` from a handle, which stays valid until `release`
+    pub fn load(&mut self, handle: usize, page: &mut [u8; PAGE_SIZE]) -> Result<(), &'static str> {
+        match self.entries.get(handle) {
+            Some(Some(Entry::Same(word))) => {
+                for chunk in page.chunks_exact_mut(8) {
+                    chunk.copy_from_slice(&word.to_ne_bytes());
+                }
+            }
+            Some(Some(Entry::Compressed(data))) => decompress(data, page).ok_or("corrupt compressed page")?,
+            _ => return Err("no such compressed page"),
+        }
+        self.stats.loads += 1;
+        Ok(())
+    }
+
+    pub fn release(&mut self, handle: usize) {
+        let Some(entry) = self.entries.get_mut(handle).and_then(Option::take) else {
+            return;
+        };
+        match entry {
+            Entry::Same(_) => self.stats.same_pages -= 1,
+            Entry::Compressed(data) => self.stats.compressed_bytes -= data.len(),
+        }
+        self.stats.stored_pages -= 1;
+        self.free.push(handle);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.stats.capacity_pages > 0
+    }
+
+    pub fn stats(&self) -> ZramStats {
+        self.stats
+    }
+}
+
+pub static ZRAM: Mutex<Zram> = Mutex::new(Zram::new());
+
+// The LZ4 block format: sequences of a token, literals, a 16-bit offset
+// back into the output, and a match length. The last sequence is literals
+// only, at least five of them, and no match starts in the last 12 bytes.
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 12;
+
+fn read32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn push_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], offset: usize, match_length: usize) {
+    let extra = match_length.saturating_sub(MIN_MATCH);
+    let token = (literals.len().min(15) << 4) as u8 | if match_length > 0 { extra.min(15) as u8 } else { 0 };
+    out.push(token);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if match_length > 0 {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if extra >= 15 {
+            push_length(out, extra - 15);
+        }
+    }
+}
+
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    let mut table = [0u16; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut at = 0;
+    while at + 12 <= input.len() {
+        let sequence = read32(input, at);
+        let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        // Positions are kept plus one, so zero means none
+        let candidate = table[hash] as usize;
+        table[hash] = (at + 1) as u16;
+        if candidate == 0 || read32(input, candidate - 1) != sequence {
+            at += 1;
+            continue;
+        }
+        let from = candidate - 1;
+        let mut length = MIN_MATCH;
+        while at + length < input.len() - 5 && input[from + length] == input[at + length] {
+            length += 1;
+        }
+        push_sequence(&mut out, &input[anchor..at], at - from, length);
+        at += length;
+        anchor = at;
+    }
+    push_sequence(&mut out, &input[anchor..], 0, 0);
+    out
+}
+
+fn read_length(input: &[u8], at: &mut usize, mut length: usize) -> Option<usize> {
+    loop {
+        let byte = *input.get(*at)?;
+        *at += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+// None unless `input` decodes to exactly a page
+fn decompress(input: &[u8], page: &mut [u8; PAGE_SIZE]) -> Option<()> {
+    let mut at = 0;
+    let mut written = 0;
+    loop {
+        let token = *input.get(at)?;
+        at += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(input, &mut at, literals)?;
+        }
+        page.get_mut(written..written + literals)?.copy_from_slice(input.get(at..at + literals)?);
+        at += literals;
+        written += literals;
+        if at == input.len() {
+            return (written == PAGE_SIZE).then_some(());
+        }
+        let offset = u16::from_le_bytes(input.get(at..at + 2)?.try_into().ok()?) as usize;
+        at += 2;
+        let mut length = (token & 0xF) as usize;
+        if length == 15 {
+            length = read_length(input, &mut at, length)?;
+        }
+        length += MIN_MATCH;
+        if offset == 0 || offset > written || written + length > PAGE_SIZE {
+            return None;
+        }
+        // Byte by byte, since a match may overlap what it is copying
+        for _ in 0..length {
+            page[written] = page[written - offset];
+            written += 1;
+        }
+    }
+}
+
+// /proc/mm/zram: stored pages, their size before and after compression,
+// pages kept as one word, pages refused, and loads
+fn proc_zram() -> String {
+    let stats = ZRAM.lock().stats();
+    format!(
+        "capacity {} kB\norig_data_size {} kB\ncompr_data_size {} kB\nsame_pages {}\nrejected {}\nloads {}\n",
+        stats.capacity_pages * 4,
+        stats.stored_pages * 4,
+        stats.compressed_bytes / 1024,
+        stats.same_pages,
+        stats.rejected,
+        stats.loads,
+    )
+}
+
+pub fn init() {
+    let size = SIZE_MB.get().max(0) as usize;
+    ZRAM.lock().stats.capacity_pages = size * 256;
+    crate::fs::procfs::register("mm/zram", proc_zram);
+    if size > 0 {
+        crate::serial_println!("zram: {} MB of compressed swap", size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Pages that compress in different ways: long runs, short periods that
+    // make overlapping matches, long literal stretches and mixes of them
+    fn pages() -> Vec<[u8; PAGE_SIZE]> {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        };
+        let mut pages = vec![[0u8; PAGE_SIZE]; 6];
+        pages[0][100] = 1;
+        for (i, byte) in pages[1].iter_mut().enumerate() {
+            *byte = b"abc"[i % 3];
+        }
+        for (i, byte) in pages[2].iter_mut().enumerate() {
+            *byte = if i < 600 { random() } else { (i / 700) as u8 };
+        }
+        let text = b"the quick brown fox jumps over the lazy dog; ";
+        for (i, byte) in pages[3].iter_mut().enumerate() {
+            *byte = if i % 512 < 20 { random() } else { text[i % text.len()] };
+        }
+        for byte in pages[4].iter_mut() {
+            *byte = random();
+        }
+        for (i, byte) in pages[5].iter_mut().enumerate() {
+            *byte = if (i / 64) % 2 == 0 { random() & 3 } else { 0xAA };
+        }
+        pages
+    }
+
+    fn roundtrip(page: &[u8; PAGE_SIZE]) -> [u8; PAGE_SIZE] {
+        let mut out = [0u8; PAGE_SIZE];
+        decompress(&compress(page), &mut out).expect("compressed page does not decode");
+        out
+    }
+
+    #[test]
+    fn test_lz4_roundtrip() {
+        for (i, page) in pages().iter().enumerate() {
+            assert!(roundtrip(page) == *page, "page {} changed", i);
+        }
+        // Runs and text shrink; noise does not
+        let pages = pages();
+        assert!(compress(&pages[0]).len() < 64);
+        assert!(compress(&pages[1]).len() < 64);
+        assert!(compress(&pages[3]).len() < MAX_COMPRESSED);
+        assert!(compress(&pages[4]).len() > PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_lz4_keeps_the_end_as_literals() {
+        // The format wants the last five bytes as literals and no match
+        // starting within the last twelve
+        for page in pages() {
+            let compressed = compress(&page);
+            let mut at = 0;
+            let mut written = 0;
+            loop {
+                let token = compressed[at];
+                at += 1;
+                let mut literals = (token >> 4) as usize;
+                if literals == 15 {
+                    literals = read_length(&compressed, &mut at, literals).unwrap();
+                }
+                at += literals;
+                written += literals;
+                if at == compressed.len() {
+                    assert!(literals >= 5);
+                    break;
+                }
+                at += 2;
+                let mut length = (token & 0xF) as usize;
+                if length == 15 {
+                    length = read_length(&compressed, &mut at, length).unwrap();
+                }
+                assert!(written + 12 <= PAGE_SIZE);
+                written += length + MIN_MATCH;
+            }
+            assert_eq!(written, PAGE_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_lz4_decodes_reference_blocks() {
+        // One literal, then a match of 4090 copying it forward one byte at a
+        // time, then five literals
+        let mut block = vec![0x1F, b'a', 1, 0];
+        block.extend_from_slice(&[255; 15]);
+        block.push(246);
+        block.push(0x50);
+        block.extend_from_slice(b"aaaaa");
+        let mut page = [0u8; PAGE_SIZE];
+        decompress(&block, &mut page).unwrap();
+        assert!(page.iter().all(|&byte| byte == b'a'));
+
+        // Literals only, with a length past 15 + 255
+        let mut block = vec![0xF0];
+        block.extend_from_slice(&[255; 16]);
+        block.push((PAGE_SIZE - 15 - 255 * 16) as u8);
+        block.extend(pages()[4].iter());
+        decompress(&block, &mut page).unwrap();
+        assert!(page == pages()[4]);
+    }
+
+    #[test]
+    fn test_lz4_rejects_malformed_input() {
+        let mut page = [0u8; PAGE_SIZE];
+        assert!(decompress(&[], &mut page).is_none());
+        // A literal length whose extension is missing
+        assert!(decompress(&[0xF0], &mut page).is_none());
+        // Too few literals
+        assert!(decompress(&[0x10, b'a'], &mut page).is_none());
+        // Literals past the end of the input
+        assert!(decompress(&[0x50, b'a'], &mut page).is_none());
+        // A match with offset zero, or reaching back before the page
+        assert!(decompress(&[0x1F, b'a', 0, 0, 0], &mut page).is_none());
+        assert!(decompress(&[0x1F, b'a', 2, 0, 0], &mut page).is_none());
+        // Matches and literals running past the end of the page
+        let mut block = vec![0x1F, b'a', 1, 0];
+        block.extend_from_slice(&[255; 16]);
+        block.push(0);
+        block.push(0x50);
+        block.extend_from_slice(b"aaaaa");
+        assert!(decompress(&block, &mut page).is_none());
+        let mut block = vec![0xF0];
+        block.extend_from_slice(&[255; 16]);
+        block.push(2);
+        block.resize(block.len() + PAGE_SIZE + 255, 0);
+        assert!(decompress(&block, &mut page).is_none());
+
+        // A valid page cut short anywhere, or with anything after it
+        let compressed = compress(&pages()[3]);
+        for length in 0..compressed.len() {
+            assert!(decompress(&compressed[..length], &mut page).is_none(), "accepted {} bytes", length);
+        }
+        let mut longer = compressed.clone();
+        longer.push(0);
+        assert!(decompress(&longer, &mut page).is_none());
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let mut zram = Zram::new();
+        zram.stats.capacity_pages = 3;
+        let pages = pages();
+
+        let mut same = [0u8; PAGE_SIZE];
+        for chunk in same.chunks_exact_mut(8) {
+            chunk.copy_from_slice(&0xDEAD_BEEF_u64.to_ne_bytes());
+        }
+        let same_handle = zram.store(&same).unwrap();
+        let text_handle = zram.store(&pages[3]).unwrap();
+        // Noise is refused, and counted
+        assert_eq!(zram.store(&pages[4]), None);
+        let stats = zram.stats();
+        assert_eq!((stats.stored_pages, stats.same_pages, stats.rejected), (2, 1, 1));
+        assert_eq!(stats.compressed_bytes, compress(&pages[3]).len());
+
+        let mut page = [0u8; PAGE_SIZE];
+        zram.load(same_handle, &mut page).unwrap();
+        assert!(page == same);
+        zram.load(text_handle, &mut page).unwrap();
+        assert!(page == pages[3]);
+        assert_eq!(zram.stats().loads, 2);
+
+        // Full, then room again once a page is released; handles are reused
+        let third = zram.store(&pages[1]).unwrap();
+        assert_eq!(zram.store(&pages[0]), None);
+        zram.release(text_handle);
+        zram.release(text_handle);
+        assert!(zram.load(text_handle, &mut page).is_err());
+        assert_eq!(zram.store(&pages[0]), Some(text_handle));
+        zram.release(same_handle);
+        zram.release(third);
+        zram.release(text_handle);
+        let stats = zram.stats();
+        assert_eq!((stats.stored_pages, stats.same_pages, stats.compressed_bytes), (0, 0, 0));
+        assert!(zram.load(99, &mut page).is_err());
+    }
+}
@@ -133,6 +133,7 @@ pub enum FileSystemAction {
     Full,
     CorruptionDetected,
     QuotaExceeded,
+    Checked,
 }
 
 #[derive(Debug, Clone)]
@@ -368,6 +369,25 @@ pub fn emit_fs_mounted(path: &str) {
     );
 }
 
+// A consistency check: corruption if anything found is left unfixed
+pub fn emit_fs_checked(volume: &str, problems: usize, fixed: usize) {
+    let (severity, action) = match (problems, fixed) {
+        (0, _) => (EventSeverity::Info, FileSystemAction::Checked),
+        (problems, fixed) if fixed == problems => (EventSeverity::Medium, FileSystemAction::Checked),
+        _ => (EventSeverity::High, FileSystemAction::CorruptionDetected),
+    };
+    emit_event(
+        EventType::FileSystem,
+        severity,
+        "chkdsk",
+        &format!("Checked {}: {} problems found, {} fixed", volume, problems, fixed),
+        EventData::FileSystemEvent(FileSystemEventData {
+            path: volume.to_string(),
+            action,
+        }),
+    );
+}
+
 pub fn emit_power_state_change(action: PowerAction) {
     emit_event(
         EventType::Power,
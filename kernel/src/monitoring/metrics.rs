@@ -235,6 +235,19 @@ pub fn update_cpu_usage(core: usize, usage: u64) {
     }
 }
 
+/// User, kernel, interrupt and idle time of each CPU, also summed into the
+/// CPU metrics
+pub fn cpu_times() -> Vec<(u32, crate::smp::cpustat::CpuTimes)> {
+    let cpus = crate::smp::cpustat::all();
+    let cpu_metrics = &METRICS_COLLECTOR.cpu_metrics;
+    let sum = |part: fn(&crate::smp::cpustat::CpuTimes) -> u64| cpus.iter().map(|(_, times)| part(times)).sum::<u64>();
+    cpu_metrics.user_time.store(sum(|times| times.user_ns), Ordering::Relaxed);
+    cpu_metrics.system_time.store(sum(|times| times.kernel_ns), Ordering::Relaxed);
+    cpu_metrics.interrupt_time.store(sum(|times| times.irq_ns), Ordering::Relaxed);
+    cpu_metrics.idle_time.store(sum(|times| times.idle_ns), Ordering::Relaxed);
+    cpus
+}
+
 pub fn increment_context_switches() {
     METRICS_COLLECTOR.cpu_metrics.context_switches.fetch_add(1, Ordering::Relaxed);
 }
@@ -330,6 +343,11 @@ pub fn export_prometheus() -> String {
     // Add system metrics
     output.push_str(&format!("cpu_usage_total {}\n", 
         METRICS_COLLECTOR.cpu_metrics.total_usage.load(Ordering::Relaxed)));
+    for (cpu, times) in cpu_times() {
+        for (mode, ns) in [("user", times.user_ns), ("system", times.kernel_ns), ("irq", times.irq_ns), ("idle", times.idle_ns)] {
+            output.push_str(&format!("cpu_time_ns_total{{cpu=\"{}\",mode=\"{}\"}} {}\n", cpu, mode, ns));
+        }
+    }
     output.push_str(&format!("memory_used_bytes {}\n",
         METRICS_COLLECTOR.memory_metrics.used_memory.load(Ordering::Relaxed)));
     output.push_str(&format!("disk_read_ops_total {}\n",
@@ -93,15 +93,28 @@ pub struct PrometheusExporter {
     endpoint: String,
 }
 
+impl PrometheusExporter {
+    /// Push metrics to `endpoint`, an http:// or https:// URL such as a
+    /// Pushgateway's `/metrics/job/<name>`
+    pub fn new(endpoint: &str) -> Self {
+        Self { endpoint: endpoint.to_string() }
+    }
+}
+
 impl TelemetryExporter for PrometheusExporter {
     fn export_trace(&self, _trace: &Trace) {
         // Prometheus doesn't directly support traces
     }
     
     fn export_metric(&self, metric: &MetricData) {
-        // Format and send metric in Prometheus format
-        let _prometheus_format = format_prometheus_metric(metric);
-        // Send to endpoint
+        // Format the metric in Prometheus' text format and POST it in the
+        // background; failures are logged by the HTTP client
+        let Ok(url) = crate::net::http::Url::parse(&self.endpoint) else {
+            return;
+        };
+        let body = format_prometheus_metric(metric).into_bytes();
+        let request = crate::net::http::client::Request::post(url, "text/plain; version=0.0.4", body);
+        crate::net::http::spawn(request, crate::net::http::Action::Discard);
     }
     
     fn name(&self) -> &str {
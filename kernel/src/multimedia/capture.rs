@@ -423,8 +423,11 @@ pub struct CaptureManager {
     devices: RwLock<Vec<Arc<RwLock<dyn CaptureDevice>>>>,
 }
 
+/// The capture devices drivers have found
+pub static CAPTURE_MANAGER: CaptureManager = CaptureManager::new();
+
 impl CaptureManager {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             devices: RwLock::new(Vec::new()),
         }
@@ -448,6 +451,10 @@ impl CaptureManager {
         self.devices.write().push(device);
     }
 
+    pub fn unregister_device(&self, device: &Arc<RwLock<dyn CaptureDevice>>) {
+        self.devices.write().retain(|d| !Arc::ptr_eq(d, device));
+    }
+
     pub fn get_device(&self, name: &str) -> Option<Arc<RwLock<dyn CaptureDevice>>> {
         self.devices.read()
             .iter()
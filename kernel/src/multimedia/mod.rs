@@ -61,6 +61,10 @@ pub enum PixelFormat {
     NV21,
     GRAY8,
     GRAY16,
+    /// Packed 4:2:2, Y0 U Y1 V, as YUY2
+    YUYV,
+    /// A JPEG image per frame
+    MJPEG,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -241,7 +241,9 @@ pub fn send_arp_request(target_ip: Ipv4Address) {
     
     // Send through network interface
     crate::serial_println!("Sending ARP request for {}", target_ip);
-    // interface::send_frame(&frame);
+    if let Err(e) = super::interface::send_frame(&frame) {
+        crate::serial_println!("ARP: cannot send request: {}", e);
+    }
 }
 
 // Send ARP reply
@@ -258,7 +260,9 @@ pub fn send_arp_reply(target_mac: MacAddress, target_ip: Ipv4Address) {
     );
     
     crate::serial_println!("Sending ARP reply to {}", target_ip);
-    // interface::send_frame(&frame);
+    if let Err(e) = super::interface::send_frame(&frame) {
+        crate::serial_println!("ARP: cannot send reply: {}", e);
+    }
 }
 
 // Resolve IP to MAC address
@@ -281,7 +285,7 @@ pub fn resolve(ip: Ipv4Address) -> Option<MacAddress> {
 
 // Helper functions (would be provided by network interface)
 fn get_our_mac() -> MacAddress {
-    MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]) // QEMU default
+    super::ethernet::get_mac_address()
 }
 
 fn get_our_ip() -> Option<Ipv4Address> {
@@ -1,6 +1,447 @@
-// Network Buffer Management
+// Packet Buffers
+//
+// A packet is built from its payload outward. The payload goes in first,
+// and each layer below pushes its header in front of it, into headroom kept
+// at the start of the first page, so nothing already written moves. Payload
+// past the first page is chained on as fragments: runs of pages that other
+// packets may share, a page being freed with its last reference, so cloning
+// a packet or cutting it into segments copies none of its payload.
+//
+// The pages come from the frame allocator, so a NIC that does scatter-gather
+// DMA sends straight from them, and receives into them: a received frame is
+// the page the NIC wrote it to, and each layer pulls its header off the
+// front.
+//
+// A packet can leave work to the NIC: finishing a TCP or UDP checksum, and
+// cutting a large TCP segment into ones the path carries (TSO). For a NIC
+// that cannot, `interface::transmit` does the same here, with
+// `finish_checksum` and `segment`.
+
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
+
+use super::ethernet::{ETHERTYPE_IPV4, ETH_HEADER_SIZE};
+use super::ip::{Ipv4Address, IPV4_HEADER_MIN_SIZE, IP_PROTO_TCP};
+use crate::memory::frame_allocator::FRAME_ALLOCATOR;
+use crate::memory::PHYS_MEM_OFFSET;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Room kept in front of a new packet for the headers pushed onto it: a
+/// virtio-net header, Ethernet, IPv4 and TCP with options
+pub const HEADROOM: usize = 128;
+
+const TCP_HEADER_MIN_SIZE: usize = 20;
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+
+bitflags::bitflags! {
+    /// What a NIC does with the packets it is given
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Offloads: u32 {
+        /// Sends a packet from its pages as they are
+        const SCATTER_GATHER = 1 << 0;
+        /// Finishes the checksum a packet asks for
+        const CHECKSUM = 1 << 1;
+        /// Cuts TCP/IPv4 packets into segments
+        const TSO = 1 << 2;
+    }
+}
+
+/// A checksum left for the NIC: the ones' complement sum from `start`,
+/// counted from the front of the packet, to its end, stored `offset` bytes
+/// past `start`. Meanwhile the field holds the pseudo-header's sum, folded
+/// but not complemented, as Linux's CHECKSUM_PARTIAL has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialChecksum {
+    pub start: usize,
+    pub offset: usize,
+}
+
+/// A page packets are built in or received into
+pub struct Page {
+    frame: PhysFrame,
+}
+
+impl Page {
+    pub fn new() -> Option<Arc<Page>> {
+        let frame = FRAME_ALLOCATOR.lock().allocate_frame()?;
+        Some(Arc::new(Page { frame }))
+    }
+
+    pub fn physical(&self) -> u64 {
+        self.frame.start_address().as_u64()
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts((PHYS_MEM_OFFSET + self.physical()) as *const u8, PAGE_SIZE) }
+    }
+
+    // Only through `Arc::get_mut`, so no other packet sees the change
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut((PHYS_MEM_OFFSET + self.physical()) as *mut u8, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR.lock().deallocate_frame(self.frame);
+    }
+}
+
+/// A run of bytes in a page
+#[derive(Clone)]
+pub struct Fragment {
+    page: Arc<Page>,
+    offset: usize,
+    len: usize,
+}
+
+impl Fragment {
+    pub fn bytes(&self) -> &[u8] {
+        &self.page.bytes()[self.offset..self.offset + self.len]
+    }
+
+    pub fn physical(&self) -> u64 {
+        self.page.physical() + self.offset as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A packet, as a first page holding its headers and fragments holding the
+/// rest of its payload
+#[derive(Clone)]
+pub struct PacketBuffer {
+    head: Arc<Page>,
+    // The packet's bytes in `head`, with headroom before them
+    start: usize,
+    end: usize,
+    fragments: Vec<Fragment>,
+    /// A checksum for the NIC to finish
+    pub checksum: Option<PartialChecksum>,
+    /// Cut into TCP segments with this much payload each
+    pub segment_size: Option<usize>,
+}
+
+impl PacketBuffer {
+    /// An empty packet; None if memory is short
+    pub fn new() -> Option<PacketBuffer> {
+        Some(PacketBuffer {
+            head: Page::new()?,
+            start: HEADROOM,
+            end: HEADROOM,
+            fragments: Vec::new(),
+            checksum: None,
+            segment_size: None,
+        })
+    }
+
+    /// A packet holding a copy of `data`
+    pub fn from_bytes(data: &[u8]) -> Option<PacketBuffer> {
+        let mut packet = PacketBuffer::new()?;
+        packet.append(data)?;
+        Some(packet)
+    }
+
+    /// The `len` bytes at `offset` in `page`, where a NIC received a frame
+    pub fn received(page: Arc<Page>, offset: usize, len: usize) -> PacketBuffer {
+        PacketBuffer {
+            head: page,
+            start: offset,
+            end: offset + len,
+            fragments: Vec::new(),
+            checksum: None,
+            segment_size: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start + self.fragments.iter().map(|fragment| fragment.len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes in the first page: the headers of a packet being built, and
+    /// all of a received frame
+    pub fn head(&self) -> &[u8] {
+        &self.head.bytes()[self.start..self.end]
+    }
+
+    /// The first page's bytes to change, copied first if another packet
+    /// shares it
+    pub fn head_mut(&mut self) -> Option<&mut [u8]> {
+        let (start, end) = (self.start, self.end);
+        Some(&mut self.head_page()?.bytes_mut()[start..end])
+    }
+
+    fn head_page(&mut self) -> Option<&mut Page> {
+        if Arc::get_mut(&mut self.head).is_none() {
+            let mut page = Page::new()?;
+            Arc::get_mut(&mut page)?.bytes_mut()[self.start..self.end].copy_from_slice(self.head());
+            self.head = page;
+        }
+        Arc::get_mut(&mut self.head)
+    }
+
+    pub fn fragments(&self) -> &[Fragment] {
+        &self.fragments
+    }
+
+    /// Each run of the packet's bytes as (physical address, length), in
+    /// order, for a NIC to gather
+    pub fn segments(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        let head = (self.head.physical() + self.start as u64, self.end - self.start);
+        core::iter::once(head)
+            .filter(|&(_, len)| len > 0)
+            .chain(self.fragments.iter().map(|fragment| (fragment.physical(), fragment.len)))
+    }
+
+    // Each run of the packet's bytes, in order
+    fn runs(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        core::iter::once(self.head()).chain(self.fragments.iter().map(Fragment::bytes))
+    }
+
+    /// The packet in one piece, for a NIC that cannot gather
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len());
+        for run in self.runs() {
+            bytes.extend_from_slice(run);
+        }
+        bytes
+    }
+
+    /// Copy `data` onto the end
+    pub fn append(&mut self, mut data: &[u8]) -> Option<()> {
+        if self.fragments.is_empty() {
+            let count = (PAGE_SIZE - self.end).min(data.len());
+            if count > 0 {
+                let end = self.end;
+                self.head_page()?.bytes_mut()[end..end + count].copy_from_slice(&data[..count]);
+                self.end += count;
+                data = &data[count..];
+            }
+        }
+        while !data.is_empty() {
+            let mut page = Page::new()?;
+            let count = PAGE_SIZE.min(data.len());
+            Arc::get_mut(&mut page)?.bytes_mut()[..count].copy_from_slice(&data[..count]);
+            self.fragments.push(Fragment { page, offset: 0, len: count });
+            data = &data[count..];
+        }
+        Some(())
+    }
+
+    /// Chain `fragment` on the end without copying it
+    pub fn append_fragment(&mut self, fragment: Fragment) {
+        if fragment.len > 0 {
+            self.fragments.push(fragment);
+        }
+    }
+
+    /// The `len` bytes at `offset`, as fragments sharing the packet's pages
+    pub fn range(&self, mut offset: usize, mut len: usize) -> Vec<Fragment> {
+        let head = Fragment { page: self.head.clone(), offset: self.start, len: self.end - self.start };
+        let mut range = Vec::new();
+        for fragment in core::iter::once(&head).chain(self.fragments.iter()) {
+            if len == 0 {
+                break;
+            }
+            if offset >= fragment.len {
+                offset -= fragment.len;
+                continue;
+            }
+            let count = (fragment.len - offset).min(len);
+            range.push(Fragment { page: fragment.page.clone(), offset: fragment.offset + offset, len: count });
+            offset = 0;
+            len -= count;
+        }
+        range
+    }
+
+    /// Room for a `len`-byte header in front of the packet, to fill in.
+    /// Without the headroom, or if another packet shares the first page,
+    /// what is there is chained behind a new first page rather than copied.
+    pub fn push(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > HEADROOM {
+            return None;
+        }
+        if self.start < len || Arc::get_mut(&mut self.head).is_none() {
+            let page = Page::new()?;
+            let old = core::mem::replace(&mut self.head, page);
+            let fragment = Fragment { page: old, offset: self.start, len: self.end - self.start };
+            if fragment.len > 0 {
+                self.fragments.insert(0, fragment);
+            }
+            self.start = HEADROOM;
+            self.end = HEADROOM;
+        }
+        self.start -= len;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.start += len;
+        }
+        let (start, end) = (self.start, self.start + len);
+        Some(&mut Arc::get_mut(&mut self.head)?.bytes_mut()[start..end])
+    }
+
+    /// Take a `len`-byte header off the front of a received frame
+    pub fn pull(&mut self, len: usize) -> Option<&[u8]> {
+        if self.end - self.start < len {
+            return None;
+        }
+        self.start += len;
+        if let Some(checksum) = &mut self.checksum {
+            checksum.start = checksum.start.saturating_sub(len);
+        }
+        Some(&self.head.bytes()[self.start - len..self.start])
+    }
+
+    // Bring the first `len` bytes into the first page, for headers split
+    // across pages
+    fn pull_up(&mut self, len: usize) -> Option<()> {
+        if self.start + len > PAGE_SIZE {
+            return None;
+        }
+        while self.end - self.start < len {
+            let fragment = self.fragments.first_mut()?;
+            let count = (len - (self.end - self.start)).min(fragment.len);
+            let bytes = fragment.bytes()[..count].to_vec();
+            fragment.offset += count;
+            fragment.len -= count;
+            if fragment.len == 0 {
+                self.fragments.remove(0);
+            }
+            let end = self.end;
+            self.head_page()?.bytes_mut()[end..end + count].copy_from_slice(&bytes);
+            self.end += count;
+        }
+        Some(())
+    }
+
+    /// Where the TCP header starts and the headers end in a TCP/IPv4 frame,
+    /// with all of them brought into the first page
+    pub fn tcp_headers(&mut self) -> Option<(usize, usize)> {
+        self.pull_up(ETH_HEADER_SIZE + IPV4_HEADER_MIN_SIZE)?;
+        let head = self.head();
+        if u16::from_be_bytes([head[12], head[13]]) != ETHERTYPE_IPV4 || head[ETH_HEADER_SIZE + 9] != IP_PROTO_TCP {
+            return None;
+        }
+        let tcp = ETH_HEADER_SIZE + (head[ETH_HEADER_SIZE] & 0x0F) as usize * 4;
+        self.pull_up(tcp + TCP_HEADER_MIN_SIZE)?;
+        let end = tcp + (self.head()[tcp + 12] >> 4) as usize * 4;
+        self.pull_up(end)?;
+        Some((tcp, end))
+    }
+
+    // The ones' complement sum of the packet from `from` to its end, folded
+    fn sum_from(&self, mut from: usize) -> u16 {
+        let mut sum: u64 = 0;
+        let mut odd = false;
+        for run in self.runs() {
+            if from >= run.len() {
+                from -= run.len();
+                continue;
+            }
+            for &byte in &run[from..] {
+                sum += if odd { byte as u64 } else { (byte as u64) << 8 };
+                odd = !odd;
+            }
+            from = 0;
+        }
+        fold(sum)
+    }
+
+    /// Fill in the checksum the packet asks for, as a NIC would
+    pub fn finish_checksum(&mut self) -> Option<()> {
+        let Some(checksum) = self.checksum else {
+            return Some(());
+        };
+        let field = checksum.start + checksum.offset;
+        self.pull_up(field + 2)?;
+        let value = !self.sum_from(checksum.start);
+        self.head_mut()?[field..field + 2].copy_from_slice(&value.to_be_bytes());
+        self.checksum = None;
+        Some(())
+    }
+
+    /// Cut a TCP/IPv4 frame asking for segmentation into frames with
+    /// `segment_size` bytes of payload, as a NIC doing TSO would. The
+    /// payload is shared, not copied, and each segment still asks for its
+    /// checksum.
+    pub fn segment(mut self) -> Option<Vec<PacketBuffer>> {
+        let Some(size) = self.segment_size.take() else {
+            return Some(alloc::vec![self]);
+        };
+        let (tcp, headers) = self.tcp_headers()?;
+        let payload = self.len() - headers;
+        if size == 0 || payload <= size {
+            return Some(alloc::vec![self]);
+        }
+
+        let header = self.head()[..headers].to_vec();
+        let ip = ETH_HEADER_SIZE;
+        let id = u16::from_be_bytes([header[ip + 4], header[ip + 5]]);
+        let source = Ipv4Address::new(header[ip + 12], header[ip + 13], header[ip + 14], header[ip + 15]);
+        let destination = Ipv4Address::new(header[ip + 16], header[ip + 17], header[ip + 18], header[ip + 19]);
+        let sequence = u32::from_be_bytes([header[tcp + 4], header[tcp + 5], header[tcp + 6], header[tcp + 7]]);
+        let flags = header[tcp + 13];
+
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while offset < payload {
+            let count = size.min(payload - offset);
+            let last = offset + count == payload;
+            let mut segment = PacketBuffer::from_bytes(&header)?;
+            for fragment in self.range(headers + offset, count) {
+                segment.append_fragment(fragment);
+            }
+
+            let bytes = segment.head_mut()?;
+            let total_length = (headers - ip + count) as u16;
+            bytes[ip + 2..ip + 4].copy_from_slice(&total_length.to_be_bytes());
+            bytes[ip + 4..ip + 6].copy_from_slice(&id.wrapping_add(segments.len() as u16).to_be_bytes());
+            bytes[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+            let ip_checksum = super::checksum(&bytes[ip..tcp]);
+            bytes[ip + 10..ip + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+            bytes[tcp + 4..tcp + 8].copy_from_slice(&sequence.wrapping_add(offset as u32).to_be_bytes());
+            if !last {
+                bytes[tcp + 13] = flags & !(TCP_FIN | TCP_PSH);
+            }
+            let pseudo = pseudo_header_sum(source, destination, IP_PROTO_TCP, headers - tcp + count);
+            bytes[tcp + 16..tcp + 18].copy_from_slice(&pseudo.to_be_bytes());
+            segment.checksum = Some(PartialChecksum { start: tcp, offset: 16 });
+
+            segments.push(segment);
+            offset += count;
+        }
+        Some(segments)
+    }
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
 
-pub struct NetworkBuffer {
-    pub data: Vec<u8>,
-}
\ No newline at end of file
+/// The sum of an IPv4 pseudo-header, folded but not complemented, for the
+/// checksum field of a packet whose checksum the NIC finishes
+pub fn pseudo_header_sum(source: Ipv4Address, destination: Ipv4Address, protocol: u8, length: usize) -> u16 {
+    let mut sum = protocol as u64 + length as u64;
+    for address in [source, destination] {
+        let bytes = address.as_bytes();
+        sum += u16::from_be_bytes([bytes[0], bytes[1]]) as u64 + u16::from_be_bytes([bytes[2], bytes[3]]) as u64;
+    }
+    fold(sum)
+}
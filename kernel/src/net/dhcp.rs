@@ -616,6 +616,26 @@ pub fn process_dhcp_reply(data: &[u8]) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Start over, as when the link has come up on another network
+pub fn restart_dhcp_client() -> Result<(), &'static str> {
+    if DHCP_CLIENT.lock().take().is_some() {
+        let _ = super::udp::unbind(PORT_DHCP_CLIENT);
+    }
+    start_dhcp_client()
+}
+
+/// Hand the client the replies that have come in for it
+pub fn poll() {
+    if DHCP_CLIENT.lock().is_none() {
+        return;
+    }
+    while let Ok(Some((_, _, data))) = super::udp::recv_from(PORT_DHCP_CLIENT) {
+        if let Err(e) = process_dhcp_reply(&data) {
+            crate::serial_println!("DHCP: {}", e);
+        }
+    }
+}
+
 pub fn get_dhcp_config() -> DhcpConfig {
     DHCP_CLIENT_CONFIG.lock().clone()
 }
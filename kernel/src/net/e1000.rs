@@ -0,0 +1,484 @@
+// Intel 8254x and 82574 network adapters
+//
+// The e1000 family, as QEMU and VirtualBox emulate it. Both rings live in
+// a page each, and the adapter is polled rather than interrupting. Every
+// receive descriptor owns a page, and a frame goes up the stack in the page
+// the adapter wrote it to. A packet is sent from its own pages, one data
+// descriptor each, and is held until the adapter marks its last descriptor
+// done.
+//
+// A packet asking for a checksum or for segmentation gets a context
+// descriptor ahead of its data, telling the adapter where the headers are
+// and what to fill in. For TSO the adapter wants the IP total length zero
+// and the TCP checksum field holding the pseudo-header's sum without the
+// length, since it puts in each segment's own.
+//
+// Each adapter is a wake source, off until enabled: suspended with wake
+// armed, it signals PME on a magic packet. Coming back from D3hot resets
+// it, so resume has it probed again.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::memory::frame_allocator::FRAME_ALLOCATOR;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER};
+use crate::power::{DevicePowerState, WakeSourceType};
+use crate::time::clocksource::now_ns;
+
+use super::buffer::{pseudo_header_sum, Offloads, PacketBuffer, Page};
+use super::ethernet::{EthernetController, EthernetFrame, MacAddress, ETH_HEADER_SIZE};
+use super::ip::{Ipv4Address, IP_PROTO_TCP};
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+// Registers
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const IMC: usize = 0x00D8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const MTA: usize = 0x5200;
+const MTA_ENTRIES: usize = 128;
+const RAL0: usize = 0x5400;
+const RAH0: usize = 0x5404;
+const WUC: usize = 0x5800;
+const WUFC: usize = 0x5808;
+const WUS: usize = 0x5810;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const STATUS_LU: u32 = 1 << 1;
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+const WUC_PME_EN: u32 = 1 << 1;
+const WUFC_MAG: u32 = 1 << 1;
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+// The IEEE 802.3 gaps, as the manuals give them for copper
+const TIPG_DEFAULT: u32 = 0x0060_200A;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const RX_DESCRIPTORS: usize = 128;
+const TX_DESCRIPTORS: usize = 256;
+// RCTL's buffer size field left at 0
+const RX_BUFFER_SIZE: usize = 2048;
+
+// Receive descriptor status and errors
+const RX_DD: u8 = 1 << 0;
+const RX_EOP: u8 = 1 << 1;
+
+// Transmit descriptors: the type of data descriptors, context ones being
+// type 0, and the command bits of each (DCMD and TUCMD)
+const DTYP_DATA: u64 = 1 << 20;
+const DCMD_EOP: u64 = 1 << 24;
+const DCMD_IFCS: u64 = 1 << 25;
+const DCMD_TSE: u64 = 1 << 26;
+const DCMD_RS: u64 = 1 << 27;
+const DCMD_DEXT: u64 = 1 << 29;
+const TUCMD_TCP: u64 = 1 << 24;
+const TUCMD_IP: u64 = 1 << 25;
+const TUCMD_TSE: u64 = 1 << 26;
+// Data descriptor options and status
+const POPTS_IXSM: u64 = 1 << 40;
+const POPTS_TXSM: u64 = 1 << 41;
+const TX_DD: u64 = 1 << 32;
+
+const RESET_TIMEOUT_MS: u64 = 100;
+
+struct E1000 {
+    // Virtual address of the registers
+    registers: u64,
+    mac: MacAddress,
+    // Physical addresses of the rings, a page each
+    rx_ring: u64,
+    tx_ring: u64,
+    // The page each receive descriptor owns, and the next one to look at
+    rx_pages: Vec<Arc<Page>>,
+    rx_next: usize,
+    // The next free transmit descriptor, and the first not yet reclaimed
+    tx_tail: usize,
+    tx_clean: usize,
+    // Packets being sent, by the descriptor that ends each
+    sending: VecDeque<(usize, PacketBuffer)>,
+}
+
+// The interface each adapter was registered as
+static BOUND: Mutex<Vec<(DeviceId, String)>> = Mutex::new(Vec::new());
+
+impl E1000 {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { read_volatile((self.registers + register as u64) as *const u32) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { write_volatile((self.registers + register as u64) as *mut u32, value) }
+    }
+
+    fn descriptor(ring: u64, index: usize) -> *mut u64 {
+        (PHYS_MEM_OFFSET + ring + (index * DESCRIPTOR_SIZE) as u64) as *mut u64
+    }
+
+    fn reset(&self) -> Result<(), &'static str> {
+        self.write(IMC, u32::MAX);
+        self.write(CTRL, self.read(CTRL) | CTRL_RST);
+        let deadline = now_ns() + RESET_TIMEOUT_MS * 1_000_000;
+        while self.read(CTRL) & CTRL_RST != 0 {
+            if now_ns() >= deadline {
+                return Err("adapter did not reset");
+            }
+            core::hint::spin_loop();
+        }
+        // Reset turns interrupts back on
+        self.write(IMC, u32::MAX);
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), &'static str> {
+        self.reset()?;
+        self.write(CTRL, self.read(CTRL) | CTRL_SLU | CTRL_ASDE);
+
+        // The adapter loads its address from the EEPROM at reset
+        let (low, high) = (self.read(RAL0), self.read(RAH0));
+        let mac = [low as u8, (low >> 8) as u8, (low >> 16) as u8, (low >> 24) as u8, high as u8, (high >> 8) as u8];
+        if mac == [0; 6] {
+            return Err("no MAC address");
+        }
+        self.mac = MacAddress::new(mac);
+        for entry in 0..MTA_ENTRIES {
+            self.write(MTA + 4 * entry, 0);
+        }
+
+        for index in 0..RX_DESCRIPTORS {
+            let page = Page::new().ok_or("out of memory for receive buffers")?;
+            self.post(index, page.physical());
+            self.rx_pages.push(page);
+        }
+        self.write(RDBAL, self.rx_ring as u32);
+        self.write(RDBAH, (self.rx_ring >> 32) as u32);
+        self.write(RDLEN, (RX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(RDH, 0);
+        self.write(RDT, RX_DESCRIPTORS as u32 - 1);
+        self.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        self.write(TDBAL, self.tx_ring as u32);
+        self.write(TDBAH, (self.tx_ring >> 32) as u32);
+        self.write(TDLEN, (TX_DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(TDH, 0);
+        self.write(TDT, 0);
+        self.write(TIPG, TIPG_DEFAULT);
+        self.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+        Ok(())
+    }
+
+    // Give receive descriptor `index` the buffer at `address`
+    fn post(&self, index: usize, address: u64) {
+        let descriptor = Self::descriptor(self.rx_ring, index);
+        unsafe {
+            write_volatile(descriptor, address);
+            write_volatile(descriptor.add(1), 0);
+        }
+    }
+
+    // Let go of the packets the adapter has sent
+    fn reclaim(&mut self) {
+        while let Some(&(last, _)) = self.sending.front() {
+            let status = unsafe { read_volatile(Self::descriptor(self.tx_ring, last).add(1)) };
+            if status & TX_DD == 0 {
+                break;
+            }
+            self.sending.pop_front();
+            self.tx_clean = (last + 1) % TX_DESCRIPTORS;
+        }
+    }
+
+    fn tx_free(&self) -> usize {
+        TX_DESCRIPTORS - 1 - (self.tx_tail + TX_DESCRIPTORS - self.tx_clean) % TX_DESCRIPTORS
+    }
+
+    fn queue(&mut self, low: u64, high: u64) -> usize {
+        let index = self.tx_tail;
+        let descriptor = Self::descriptor(self.tx_ring, index);
+        unsafe {
+            write_volatile(descriptor, low);
+            write_volatile(descriptor.add(1), high);
+        }
+        self.tx_tail = (index + 1) % TX_DESCRIPTORS;
+        index
+    }
+
+    // The context descriptor for a packet's offloads, and the options its
+    // data descriptors take
+    fn context(packet: &mut PacketBuffer) -> Result<Option<(u64, u64, u64)>, &'static str> {
+        let Some(checksum) = packet.checksum else {
+            return Ok(None);
+        };
+        let tucss = checksum.start as u64;
+        let tucso = (checksum.start + checksum.offset) as u64;
+        let Some(mss) = packet.segment_size else {
+            let low = (tucss << 32) | (tucso << 40);
+            return Ok(Some((low, DCMD_DEXT, POPTS_TXSM)));
+        };
+
+        let (tcp, headers) = packet.tcp_headers().ok_or("not a TCP/IPv4 packet")?;
+        let payload = (packet.len() - headers) as u64;
+        let head = packet.head_mut().ok_or("out of memory for a packet")?;
+        let ip = ETH_HEADER_SIZE;
+        let source = Ipv4Address::new(head[ip + 12], head[ip + 13], head[ip + 14], head[ip + 15]);
+        let destination = Ipv4Address::new(head[ip + 16], head[ip + 17], head[ip + 18], head[ip + 19]);
+        head[ip + 2..ip + 4].copy_from_slice(&[0, 0]);
+        head[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+        let pseudo = pseudo_header_sum(source, destination, IP_PROTO_TCP, 0);
+        head[tcp + 16..tcp + 18].copy_from_slice(&pseudo.to_be_bytes());
+
+        let (ipcss, ipcso, ipcse) = (ip as u64, (ip + 10) as u64, (tcp - 1) as u64);
+        let low = ipcss | (ipcso << 8) | (ipcse << 16) | (tucss << 32) | (tucso << 40);
+        let command = DCMD_DEXT | TUCMD_TSE | TUCMD_IP | TUCMD_TCP;
+        let high = payload | command | ((headers as u64) << 40) | ((mss as u64) << 48);
+        Ok(Some((low, high, POPTS_IXSM | POPTS_TXSM | DCMD_TSE)))
+    }
+}
+
+impl Drop for E1000 {
+    // The adapter lets go of the rings and pages before they are freed
+    fn drop(&mut self) {
+        let _ = self.reset();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        for ring in [self.rx_ring, self.tx_ring] {
+            allocator.deallocate_frame(PhysFrame::containing_address(x86_64::PhysAddr::new(ring)));
+        }
+    }
+}
+
+impl EthernetController for E1000 {
+    fn get_mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        self.transmit(PacketBuffer::from_bytes(&frame.to_bytes()).ok_or("out of memory for a packet")?)
+    }
+
+    fn receive_frame(&mut self) -> Option<EthernetFrame> {
+        EthernetFrame::from_bytes(self.receive()?.head()).ok()
+    }
+
+    fn set_promiscuous(&mut self, enabled: bool) {
+        let rctl = self.read(RCTL) & !(RCTL_UPE | RCTL_MPE);
+        self.write(RCTL, if enabled { rctl | RCTL_UPE | RCTL_MPE } else { rctl });
+    }
+
+    fn get_link_status(&self) -> bool {
+        self.read(STATUS) & STATUS_LU != 0
+    }
+
+    fn offloads(&self) -> Offloads {
+        Offloads::SCATTER_GATHER | Offloads::CHECKSUM | Offloads::TSO
+    }
+
+    fn transmit(&mut self, mut packet: PacketBuffer) -> Result<(), &'static str> {
+        self.reclaim();
+        let context = Self::context(&mut packet)?;
+        let segments: Vec<(u64, usize)> = packet.segments().collect();
+        if segments.len() + context.is_some() as usize > self.tx_free() {
+            return Err("transmit ring full");
+        }
+        let options = match context {
+            Some((low, high, options)) => {
+                self.queue(low, high);
+                options
+            }
+            None => 0,
+        };
+        let mut last = self.tx_tail;
+        for (position, &(address, length)) in segments.iter().enumerate() {
+            let mut command = DTYP_DATA | DCMD_DEXT | DCMD_IFCS | options;
+            if position + 1 == segments.len() {
+                command |= DCMD_EOP | DCMD_RS;
+            }
+            last = self.queue(address, length as u64 | command);
+        }
+        self.sending.push_back((last, packet));
+        // The adapter must see the descriptors before the tail that covers them
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.write(TDT, self.tx_tail as u32);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<PacketBuffer> {
+        loop {
+            let index = self.rx_next;
+            let descriptor = Self::descriptor(self.rx_ring, index);
+            let high = unsafe { read_volatile(descriptor.add(1)) };
+            let (length, status, errors) = (high as u16 as usize, (high >> 32) as u8, (high >> 40) as u8);
+            if status & RX_DD == 0 {
+                return None;
+            }
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            // Frames that span buffers are larger than this stack sends, and
+            // are dropped with the bad ones
+            let packet = if status & RX_EOP != 0 && errors == 0 && length <= RX_BUFFER_SIZE {
+                // Without a page to replace it with, the frame waits
+                let page = Page::new()?;
+                let full = core::mem::replace(&mut self.rx_pages[index], page);
+                Some(PacketBuffer::received(full, 0, length))
+            } else {
+                None
+            };
+            self.post(index, self.rx_pages[index].physical());
+            self.rx_next = (index + 1) % RX_DESCRIPTORS;
+            self.write(RDT, index as u32);
+            if packet.is_some() {
+                return packet;
+            }
+        }
+    }
+}
+
+// The registers' physical address: BAR0, which is memory, 64-bit on some
+fn register_base(pci: &PciDevice) -> Option<u64> {
+    let low = *pci.bars.first()?;
+    if low & 1 != 0 {
+        return None;
+    }
+    let mut base = (low & !0xF) as u64;
+    if (low >> 1) & 3 == 2 {
+        base |= (*pci.bars.get(1)? as u64) << 32;
+    }
+    if base == 0 { None } else { Some(base) }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+// The mapped registers, for the power callbacks, which have no E1000 to hand
+fn registers(device: &Device) -> Result<*mut u32, &'static str> {
+    let Ident::Pci { location, .. } = device.ident else {
+        return Err("not a PCI function");
+    };
+    let base = pci_function(location).as_ref().and_then(register_base).ok_or("no register BAR")?;
+    Ok((PHYS_MEM_OFFSET + base) as *mut u32)
+}
+
+/// Binds e1000 adapters
+pub static PCI_DRIVER: E1000Driver = E1000Driver;
+
+pub struct E1000Driver;
+
+impl Driver for E1000Driver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // 82540EM, QEMU's default, and the 82545EM VirtualBox and VMware offer
+            Match::PciId { vendor: VENDOR_INTEL, device: 0x100E },
+            Match::PciId { vendor: VENDOR_INTEL, device: 0x100F },
+            // 82574L, QEMU's e1000e
+            Match::PciId { vendor: VENDOR_INTEL, device: 0x10D3 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        let base = register_base(&pci).ok_or(ProbeError::Failed("no register BAR"))?;
+        PCIE_CONTROLLER.lock().enable_device(&pci);
+        let (rx_ring, tx_ring) = {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            match (allocator.allocate_frame(), allocator.allocate_frame()) {
+                (Some(rx), Some(tx)) => (rx.start_address().as_u64(), tx.start_address().as_u64()),
+                (rx, tx) => {
+                    rx.into_iter().chain(tx).for_each(|frame| allocator.deallocate_frame(frame));
+                    return Err(ProbeError::Failed("out of memory"));
+                }
+            }
+        };
+        for ring in [rx_ring, tx_ring] {
+            unsafe { core::ptr::write_bytes((PHYS_MEM_OFFSET + ring) as *mut u8, 0, 4096) };
+        }
+        let mut net = E1000 {
+            registers: PHYS_MEM_OFFSET + base,
+            mac: MacAddress::ZERO,
+            rx_ring,
+            tx_ring,
+            rx_pages: Vec::with_capacity(RX_DESCRIPTORS),
+            rx_next: 0,
+            tx_tail: 0,
+            tx_clean: 0,
+            sending: VecDeque::new(),
+        };
+        net.init().map_err(ProbeError::Failed)?;
+        crate::serial_println!("e1000: {} at {:#x}", device.name, base);
+        let name = super::interface::register("eth", Box::new(net));
+        BOUND.lock().push((device.id, name));
+        crate::power::device::register_wake_source(device.id, WakeSourceType::Network, false);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        BOUND.lock().retain(|(id, name)| {
+            if *id == device.id {
+                super::interface::unregister(name);
+            }
+            *id != device.id
+        });
+    }
+
+    fn suspend(&self, device: &Device, _state: DevicePowerState, wake: bool) -> Result<(), &'static str> {
+        let registers = registers(device)?;
+        let (wuc, wufc) = if wake { (WUC_PME_EN, WUFC_MAG) } else { (0, 0) };
+        unsafe {
+            write_volatile(registers.add(WUFC / 4), wufc);
+            write_volatile(registers.add(WUC / 4), wuc);
+        }
+        Ok(())
+    }
+
+    fn resume(&self, device: &Device) -> Result<(), &'static str> {
+        let registers = registers(device)?;
+        let receiving = unsafe {
+            write_volatile(registers.add(WUC / 4), 0);
+            write_volatile(registers.add(WUFC / 4), 0);
+            // Clear what woke it
+            write_volatile(registers.add(WUS / 4), !0);
+            read_volatile(registers.add(RCTL / 4)) & RCTL_EN != 0
+        };
+        if !receiving {
+            return Err("adapter was reset");
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 // Ethernet Layer Implementation
 use alloc::vec::Vec;
 use core::fmt;
+use super::buffer::{Offloads, PacketBuffer};
+use super::rss::RssConfig;
 
 // Ethernet constants
 pub const ETH_HEADER_SIZE: usize = 14;
@@ -118,11 +120,7 @@ impl EthernetFrame {
             .ok_or("Invalid source MAC")?;
         let ethertype = u16::from_be_bytes([data[12], data[13]]);
         
-        let header = EthernetHeader {
-            dest_mac,
-            src_mac,
-            ethertype,
-        };
+        let header = EthernetHeader::new(dest_mac, src_mac, ethertype);
         
         let payload = data[ETH_HEADER_SIZE..].to_vec();
         
@@ -135,7 +133,7 @@ impl EthernetFrame {
         // Add header
         frame.extend_from_slice(self.header.dest_mac.as_bytes());
         frame.extend_from_slice(self.header.src_mac.as_bytes());
-        frame.extend_from_slice(&self.header.ethertype.to_be_bytes());
+        frame.extend_from_slice(&self.header.ethertype().to_be_bytes());
         
         // Add payload
         frame.extend_from_slice(&self.payload);
@@ -160,36 +158,78 @@ pub trait EthernetController {
     fn receive_frame(&mut self) -> Option<EthernetFrame>;
     fn set_promiscuous(&mut self, enabled: bool);
     fn get_link_status(&self) -> bool;
+    
+    /// The work the NIC takes on; `interface::transmit` does the rest first
+    fn offloads(&self) -> Offloads {
+        Offloads::empty()
+    }
+    
+    /// Send a frame built in a packet buffer. A NIC that cannot gather gets
+    /// it in one piece.
+    fn transmit(&mut self, packet: PacketBuffer) -> Result<(), &'static str> {
+        self.send_frame(&EthernetFrame::from_bytes(&packet.to_vec())?)
+    }
+    
+    /// A received frame, in a packet buffer
+    fn receive(&mut self) -> Option<PacketBuffer> {
+        PacketBuffer::from_bytes(&self.receive_frame()?.to_bytes())
+    }
+    
+    /// Receive and transmit queue pairs, each polled on its own
+    fn queues(&self) -> usize {
+        1
+    }
+    
+    /// A frame received on `queue`
+    fn receive_queue(&mut self, queue: usize) -> Option<PacketBuffer> {
+        if queue == 0 { self.receive() } else { None }
+    }
+    
+    /// Send a frame on `queue`
+    fn transmit_queue(&mut self, _queue: usize, packet: PacketBuffer) -> Result<(), &'static str> {
+        self.transmit(packet)
+    }
+    
+    /// Spread received flows over the queues as `config` says
+    fn set_rss(&mut self, _config: &RssConfig) -> Result<(), &'static str> {
+        Err("RSS not supported")
+    }
 }
 
 // Get our MAC address
 pub fn get_mac_address() -> MacAddress {
-    // For now, return a static MAC address
-    // In a real implementation, this would query the network interface
-    MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]) // QEMU default
+    // The primary interface's, or QEMU's default before there is one
+    super::interface::mac_address().unwrap_or(MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]))
 }
 
 // Process incoming Ethernet frame
-pub fn process_frame(frame: EthernetFrame) {
+pub fn process_packet(mut packet: PacketBuffer) {
     use super::arp;
     use super::ip;
     
-    match frame.header.ethertype() {
+    let length = packet.len();
+    let Some(header) = packet.pull(ETH_HEADER_SIZE) else {
+        super::update_stats_error();
+        return;
+    };
+    let ethertype = u16::from_be_bytes([header[12], header[13]]);
+    
+    match ethertype {
         ETHERTYPE_ARP => {
             crate::serial_println!("Received ARP frame");
-            arp::process_arp_packet(&frame.payload);
+            arp::process_arp_packet(packet.head());
         }
         ETHERTYPE_IPV4 => {
             crate::serial_println!("Received IPv4 frame");
-            ip::process_ip_packet(&frame.payload);
+            ip::process_ip_packet(packet.head());
         }
         ETHERTYPE_IPV6 => {
             crate::serial_println!("Received IPv6 frame (not supported)");
         }
         _ => {
-            crate::serial_println!("Unknown EtherType: 0x{:04x}", frame.header.ethertype());
+            crate::serial_println!("Unknown EtherType: 0x{:04x}", ethertype);
         }
     }
     
-    super::update_stats_received(frame.len());
-}
\ No newline at end of file
+    super::update_stats_received(length);
+}
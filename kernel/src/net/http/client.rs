@@ -0,0 +1,470 @@
+// HTTP/1.1 client
+//
+// A `Fetch` sends one request and reads back its response, following
+// redirects on the way. Connections whose response said they could stay
+// open wait in a pool for the next request to the same server; one that
+// turns out to have been closed meanwhile is retried on a new connection.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::net::tcp::{self, TcpState};
+use crate::net::tls;
+use crate::time::clocksource::now_ns;
+
+use super::{header, Response, Url};
+
+// How long a server has to accept a plain connection
+const CONNECT_TIMEOUT_NS: u64 = 10_000_000_000;
+// How long a server may go quiet before the response is given up on
+const RESPONSE_TIMEOUT_NS: u64 = 30_000_000_000;
+const MAX_REDIRECTS: u8 = 5;
+// Most taken from the receive buffer at once
+const RECEIVE_CHUNK: usize = 16384;
+const MAX_HEAD: usize = 65536;
+const MAX_BODY: usize = 64 * 1024 * 1024;
+// Idle connections kept, and for how long
+const MAX_IDLE: usize = 4;
+const IDLE_TIMEOUT_NS: u64 = 30_000_000_000;
+
+/// A request to send
+pub struct Request {
+    pub method: &'static str,
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn get(url: Url) -> Request {
+        Request { method: "GET", url, headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn head(url: Url) -> Request {
+        Request { method: "HEAD", url, headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn post(url: Url, content_type: &str, body: Vec<u8>) -> Request {
+        Request::get(url).header("Content-Type", content_type).with_body("POST", body)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Request {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn with_body(mut self, method: &'static str, body: Vec<u8>) -> Request {
+        self.method = method;
+        self.body = body;
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}", self.method, self.url.path, self.url.host);
+        let default_port = if self.url.tls { 443 } else { 80 };
+        if self.url.port != default_port {
+            head.push_str(&format!(":{}", self.url.port));
+        }
+        head.push_str("\r\nUser-Agent: ReactOS-Rust\r\nAccept-Encoding: identity\r\n");
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !self.body.is_empty() || self.method == "POST" {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut data = head.into_bytes();
+        data.extend_from_slice(&self.body);
+        data
+    }
+}
+
+// The server a connection goes to
+#[derive(Clone, PartialEq, Eq)]
+struct Origin {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    fn of(url: &Url) -> Origin {
+        Origin { tls: url.tls, host: url.host.clone(), port: url.port }
+    }
+}
+
+enum Connection {
+    Plain { conn: u64, output: Vec<u8>, sent: usize, opened: u64 },
+    Tls(tls::Stream),
+}
+
+impl Connection {
+    fn open(origin: &Origin) -> Result<Connection, &'static str> {
+        if origin.tls {
+            return Ok(Connection::Tls(tls::Stream::connect(&origin.host, origin.port)?));
+        }
+        let address = crate::net::dns::resolve_hostname(&origin.host).ok_or("host not found")?;
+        let conn = tcp::connect(crate::net::socket::ephemeral_port(), address, origin.port)?;
+        Ok(Connection::Plain { conn, output: Vec::new(), sent: 0, opened: now_ns() })
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        match self {
+            Connection::Plain { output, .. } => output.extend_from_slice(data),
+            Connection::Tls(stream) => stream.send(data),
+        }
+    }
+
+    fn poll(&mut self) -> Result<(), &'static str> {
+        let (conn, output, sent, opened) = match self {
+            Connection::Plain { conn, output, sent, opened } => (*conn, output, sent, *opened),
+            Connection::Tls(stream) => return stream.poll(),
+        };
+        match tcp::state(conn) {
+            Some(TcpState::Established) => {}
+            Some(TcpState::SynSent | TcpState::SynReceived) => {
+                if now_ns().saturating_sub(opened) > CONNECT_TIMEOUT_NS {
+                    return Err("server did not answer");
+                }
+                return Ok(());
+            }
+            // What the server sent before closing is still to be read
+            _ => return Ok(()),
+        }
+        let window = tcp::send_window(conn).min(output.len() - *sent);
+        if window > 0 {
+            tcp::send(conn, &output[*sent..*sent + window])?;
+            *sent += window;
+        }
+        if *sent == output.len() {
+            output.clear();
+            *sent = 0;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Vec<u8> {
+        match self {
+            Connection::Plain { conn, .. } => {
+                let mut data = Vec::new();
+                while let Ok(chunk) = tcp::recv(*conn, RECEIVE_CHUNK) {
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                data
+            }
+            Connection::Tls(stream) => stream.receive(),
+        }
+    }
+
+    fn peer_closed(&self) -> bool {
+        match self {
+            Connection::Plain { conn, .. } => !matches!(
+                tcp::state(*conn),
+                Some(TcpState::SynSent | TcpState::SynReceived | TcpState::Established)
+            ),
+            Connection::Tls(stream) => stream.peer_closed(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Connection::Plain { conn, .. } = self {
+            let _ = tcp::close(*conn);
+        }
+    }
+}
+
+// Connections kept open after a response, with when they went idle
+static IDLE: Mutex<Vec<(Origin, Connection, u64)>> = Mutex::new(Vec::new());
+
+fn take_idle(origin: &Origin) -> Option<Connection> {
+    let mut idle = IDLE.lock();
+    let index = idle.iter().position(|(candidate, connection, _)| candidate == origin && !connection.peer_closed())?;
+    Some(idle.remove(index).1)
+}
+
+fn put_idle(origin: Origin, connection: Connection) {
+    let mut idle = IDLE.lock();
+    if idle.len() == MAX_IDLE {
+        idle.remove(0);
+    }
+    idle.push((origin, connection, now_ns()));
+}
+
+/// Close connections that have been idle too long or that the server has
+/// closed
+pub fn prune() {
+    let now = now_ns();
+    IDLE.lock().retain_mut(|(_, connection, since)| {
+        // A TLS stream takes in the server's close_notify when polled
+        now.saturating_sub(*since) < IDLE_TIMEOUT_NS && connection.poll().is_ok() && !connection.peer_closed()
+    });
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    Size,
+    Data(usize),
+    // The CRLF after a chunk's data
+    DataEnd,
+    Trailer,
+}
+
+// How the end of a body is found
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Body {
+    Length(usize),
+    Chunked(Chunk),
+    UntilClose,
+}
+
+impl Body {
+    // Move what `input` holds of the body to `body`, true once it is all
+    // there
+    fn decode(&mut self, input: &mut Vec<u8>, body: &mut Vec<u8>) -> Result<bool, &'static str> {
+        loop {
+            match self {
+                Body::Length(remaining) => {
+                    let length = (*remaining).min(input.len());
+                    body.extend(input.drain(..length));
+                    *remaining -= length;
+                    return Ok(*remaining == 0);
+                }
+                Body::UntilClose => {
+                    body.append(input);
+                    return Ok(false);
+                }
+                Body::Chunked(Chunk::Data(remaining)) => {
+                    let length = (*remaining).min(input.len());
+                    body.extend(input.drain(..length));
+                    *remaining -= length;
+                    if *remaining > 0 {
+                        return Ok(false);
+                    }
+                    *self = Body::Chunked(Chunk::DataEnd);
+                }
+                Body::Chunked(Chunk::DataEnd) => {
+                    match input.get(..2) {
+                        Some(b"\r\n") => input.drain(..2),
+                        Some(_) => return Err("malformed chunk"),
+                        None => return Ok(false),
+                    };
+                    *self = Body::Chunked(Chunk::Size);
+                }
+                Body::Chunked(state) => {
+                    let Some(end) = input.windows(2).position(|pair| pair == b"\r\n") else {
+                        if input.len() > MAX_HEAD {
+                            return Err("malformed chunk");
+                        }
+                        return Ok(false);
+                    };
+                    let line: Vec<u8> = input.drain(..end + 2).take(end).collect();
+                    if *state == Chunk::Trailer {
+                        if line.is_empty() {
+                            return Ok(true);
+                        }
+                        continue;
+                    }
+                    // Extensions after the size are ignored
+                    let size = core::str::from_utf8(&line).ok().and_then(|line| line.split(';').next());
+                    let size = size.and_then(|size| usize::from_str_radix(size.trim(), 16).ok());
+                    *self = match size.ok_or("malformed chunk")? {
+                        0 => Body::Chunked(Chunk::Trailer),
+                        size => Body::Chunked(Chunk::Data(size)),
+                    };
+                }
+            }
+        }
+    }
+}
+
+// The status line and headers of a response
+struct Head {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+fn parse_head(text: &[u8]) -> Result<Head, &'static str> {
+    let text = core::str::from_utf8(text).map_err(|_| "malformed response")?;
+    let mut lines = text.split("\r\n");
+    let mut status_line = lines.next().unwrap_or_default().splitn(3, ' ');
+    let version = status_line.next().unwrap_or_default();
+    if !version.starts_with("HTTP/1.") {
+        return Err("not an HTTP/1 response");
+    }
+    let status = status_line.next().and_then(|status| status.parse().ok()).ok_or("malformed response")?;
+    let reason = status_line.next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or("malformed header")?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let connection = header(&headers, "Connection").unwrap_or_default().to_ascii_lowercase();
+    let keep_alive =
+        if version == "HTTP/1.0" { connection.contains("keep-alive") } else { !connection.contains("close") };
+    Ok(Head { status, reason, headers, keep_alive })
+}
+
+/// A request on its way, and its response coming back
+pub struct Fetch {
+    request: Request,
+    connection: Option<Connection>,
+    // Whether the connection came from the pool, and whether a closed one
+    // has already been retried
+    reused: bool,
+    retried: bool,
+    redirects: u8,
+    last_activity: u64,
+    input: Vec<u8>,
+    head: Option<(Head, Body)>,
+    body: Vec<u8>,
+}
+
+impl Fetch {
+    pub fn new(request: Request) -> Fetch {
+        Fetch {
+            request,
+            connection: None,
+            reused: false,
+            retried: false,
+            redirects: 0,
+            last_activity: now_ns(),
+            input: Vec::new(),
+            head: None,
+            body: Vec::new(),
+        }
+    }
+
+    /// Bytes of the body so far
+    pub fn received(&self) -> usize {
+        self.body.len()
+    }
+
+    /// Send and receive what can be, returning the response once it is
+    /// whole
+    pub fn poll(&mut self) -> Result<Option<Response>, &'static str> {
+        if self.connection.is_none() {
+            let origin = Origin::of(&self.request.url);
+            let (mut connection, reused) = match take_idle(&origin) {
+                Some(connection) => (connection, true),
+                None => (Connection::open(&origin)?, false),
+            };
+            connection.send(&self.request.encode());
+            self.connection = Some(connection);
+            self.reused = reused;
+            self.last_activity = now_ns();
+        }
+        let Some(connection) = self.connection.as_mut() else { return Ok(None) };
+        if let Err(reason) = connection.poll() {
+            return self.retry(reason);
+        }
+        let data = connection.receive();
+        let closed = connection.peer_closed();
+        if !data.is_empty() {
+            self.input.extend_from_slice(&data);
+            self.last_activity = now_ns();
+        } else if now_ns().saturating_sub(self.last_activity) > RESPONSE_TIMEOUT_NS {
+            return Err("server stopped answering");
+        }
+
+        if self.head.is_none() && !self.read_head()? {
+            return if closed { self.retry("server closed the connection") } else { Ok(None) };
+        }
+        let Some((head, mut body)) = self.head.take() else { return Ok(None) };
+        let done = body.decode(&mut self.input, &mut self.body)?;
+        if self.body.len() > MAX_BODY {
+            return Err("response too large");
+        }
+        if !done && !(closed && body == Body::UntilClose) {
+            if closed {
+                return Err("server closed the connection before the response ended");
+            }
+            self.head = Some((head, body));
+            return Ok(None);
+        }
+
+        let connection = self.connection.take();
+        if let Some(connection) = connection.filter(|_| head.keep_alive && body != Body::UntilClose && !closed) {
+            put_idle(Origin::of(&self.request.url), connection);
+        }
+        let response = Response {
+            status: head.status,
+            reason: head.reason,
+            headers: head.headers,
+            body: core::mem::take(&mut self.body),
+        };
+        self.redirect(response)
+    }
+
+    // Whether the head is in, taking it if it is; informational responses
+    // are skipped
+    fn read_head(&mut self) -> Result<bool, &'static str> {
+        loop {
+            let Some(end) = self.input.windows(4).position(|window| window == b"\r\n\r\n") else {
+                if self.input.len() > MAX_HEAD {
+                    return Err("response head too long");
+                }
+                return Ok(false);
+            };
+            let head = parse_head(&self.input[..end])?;
+            self.input.drain(..end + 4);
+            if (100..200).contains(&head.status) {
+                continue;
+            }
+            let body = if self.request.method == "HEAD" || head.status == 204 || head.status == 304 {
+                Body::Length(0)
+            } else if header(&head.headers, "Transfer-Encoding").is_some_and(|coding| coding.contains("chunked")) {
+                Body::Chunked(Chunk::Size)
+            } else if let Some(length) = header(&head.headers, "Content-Length") {
+                Body::Length(length.parse().map_err(|_| "malformed Content-Length")?)
+            } else {
+                Body::UntilClose
+            };
+            self.head = Some((head, body));
+            return Ok(true);
+        }
+    }
+
+    // A pooled connection the server closed while idle gets one more try
+    // on a new one
+    fn retry(&mut self, reason: &'static str) -> Result<Option<Response>, &'static str> {
+        if !self.reused || self.retried || !self.input.is_empty() {
+            return Err(reason);
+        }
+        self.connection = None;
+        self.retried = true;
+        Ok(None)
+    }
+
+    fn redirect(&mut self, response: Response) -> Result<Option<Response>, &'static str> {
+        let location = match (response.status, response.header("Location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => location,
+            _ => return Ok(Some(response)),
+        };
+        if self.redirects == MAX_REDIRECTS {
+            return Err("too many redirects");
+        }
+        self.request.url = self.request.url.join(location)?;
+        // 303 always means GET, and 301 and 302 have long meant it for POST
+        if response.status == 303 || (self.request.method == "POST" && response.status <= 302) {
+            self.request.method = "GET";
+            self.request.body.clear();
+            self.request.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+        }
+        self.redirects += 1;
+        self.retried = false;
+        self.input.clear();
+        Ok(None)
+    }
+}
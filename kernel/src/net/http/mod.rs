@@ -0,0 +1,314 @@
+//! HTTP/1.1
+//!
+//! A client for the kernel's own use, fetching over plain TCP or TLS,
+//! following redirects and keeping connections open between requests to
+//! the same server, and a small server that shows /proc to a browser.
+//!
+//! Both run from the main loop. A fetch is started with `spawn` and
+//! carried on by `poll` until its response arrives, which is then saved
+//! to a file, used to set the clock, or dropped, as the caller asked.
+
+pub mod client;
+pub mod server;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::time::clocksource::now_ns;
+use crate::time::DateTime;
+use client::{Fetch, Request};
+
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Where a request goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// The path and query, starting with '/'
+    pub path: String,
+}
+
+impl Url {
+    /// An http:// or https:// URL; there is no user information or IPv6
+    pub fn parse(text: &str) -> Result<Url, &'static str> {
+        let (tls, rest) = if let Some(rest) = text.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = text.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err("not an http:// or https:// URL");
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, "/"),
+        };
+        // The fragment is the browser's business
+        let path = path.split('#').next().unwrap_or_default();
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "bad port in URL")?),
+            None => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err("bad host in URL");
+        }
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+        Ok(Url { tls, host: host.to_ascii_lowercase(), port, path })
+    }
+
+    /// Where a Location header, which may be relative, points
+    pub fn join(&self, location: &str) -> Result<Url, &'static str> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Url::parse(location);
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            return Url::parse(&format!("{}://{}", if self.tls { "https" } else { "http" }, rest));
+        }
+        let path = if location.starts_with('/') {
+            location.to_string()
+        } else {
+            let directory = self.path.split('?').next().unwrap_or_default();
+            let directory = &directory[..directory.rfind('/').map_or(0, |at| at + 1)];
+            format!("{}{}", directory, location)
+        };
+        Ok(Url { path: remove_dot_segments(&path), ..self.clone() })
+    }
+}
+
+// Resolve the "." and ".." in a path, leaving the query alone
+fn remove_dot_segments(path: &str) -> String {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').skip(1).peekable();
+    while let Some(segment) = parts.next() {
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    segments.pop();
+                }
+                // A path ending in one names a directory
+                if parts.peek().is_none() {
+                    segments.push("");
+                }
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut resolved = format!("/{}", segments.join("/"));
+    if let Some(query) = query {
+        resolved.push('?');
+        resolved.push_str(query);
+    }
+    resolved
+}
+
+impl core::fmt::Display for Url {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let (scheme, default) = if self.tls { ("https", 443) } else { ("http", 80) };
+        write!(f, "{}://{}", scheme, self.host)?;
+        if self.port != default {
+            write!(f, ":{}", self.port)?;
+        }
+        write!(f, "{}", self.path)
+    }
+}
+
+/// A response, with its body whole and its chunking undone
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A header's value; names are not case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// When the server sent it, from the Date header
+    pub fn date(&self) -> Option<DateTime> {
+        parse_date(self.header("Date")?)
+    }
+}
+
+pub(crate) fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+/// A time as HTTP writes it, like `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_date(time: &DateTime) -> String {
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[time.weekday() as usize],
+        time.day,
+        MONTHS[time.month as usize - 1],
+        time.year,
+        time.hour,
+        time.minute,
+        time.second
+    )
+}
+
+// The form servers must send; the two obsolete ones are not taken
+fn parse_date(text: &str) -> Option<DateTime> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let [_, day, month, year, time, "GMT"] = fields[..] else {
+        return None;
+    };
+    let mut clock = time.split(':').map(|field| field.parse::<u8>().ok());
+    let time = DateTime {
+        year: year.parse().ok()?,
+        month: MONTHS.iter().position(|name| *name == month)? as u8 + 1,
+        day: day.parse().ok()?,
+        hour: clock.next()??,
+        minute: clock.next()??,
+        second: clock.next()??,
+        millisecond: 0,
+    };
+    time.is_valid().then_some(time)
+}
+
+/// What becomes of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Write the body to this file
+    Save(String),
+    /// Set the clock from the Date header
+    SetClock,
+    /// Nothing; only failures are logged
+    Discard,
+}
+
+struct Transfer {
+    id: u32,
+    url: String,
+    action: Action,
+    fetch: Fetch,
+    started_ns: u64,
+}
+
+static TRANSFERS: Mutex<Vec<Transfer>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// A transfer, as `transfers` lists it
+pub struct TransferInfo {
+    pub id: u32,
+    pub url: String,
+    pub action: Action,
+    pub received: usize,
+    pub started_ns: u64,
+}
+
+/// Start the server if the command line asks for it
+pub fn init() {
+    server::init();
+}
+
+/// Fetch in the background, returning the transfer's number
+pub fn spawn(request: Request, action: Action) -> u32 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let url = request.url.to_string();
+    TRANSFERS.lock().push(Transfer { id, url, action, fetch: Fetch::new(request), started_ns: now_ns() });
+    id
+}
+
+pub fn transfers() -> Vec<TransferInfo> {
+    TRANSFERS
+        .lock()
+        .iter()
+        .map(|transfer| TransferInfo {
+            id: transfer.id,
+            url: transfer.url.clone(),
+            action: transfer.action.clone(),
+            received: transfer.fetch.received(),
+            started_ns: transfer.started_ns,
+        })
+        .collect()
+}
+
+pub fn cancel(id: u32) -> Result<(), &'static str> {
+    let mut transfers = TRANSFERS.lock();
+    let index = transfers.iter().position(|transfer| transfer.id == id).ok_or("no such transfer")?;
+    transfers.remove(index);
+    Ok(())
+}
+
+/// Carry on transfers and serve requests; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+
+    let mut done = Vec::new();
+    TRANSFERS.lock().retain_mut(|transfer| match transfer.fetch.poll() {
+        Ok(None) => true,
+        Ok(Some(response)) => {
+            done.push((transfer.url.clone(), transfer.action.clone(), response));
+            false
+        }
+        Err(reason) => {
+            crate::pr_warn!("http: {}: {}", transfer.url, reason);
+            false
+        }
+    });
+    // Files are written with the transfers unlocked
+    for (url, action, response) in done {
+        finish(&url, action, response);
+    }
+    client::prune();
+    server::poll();
+}
+
+fn finish(url: &str, action: Action, response: Response) {
+    if !response.is_success() {
+        crate::pr_warn!("http: {}: {} {}", url, response.status, response.reason);
+        return;
+    }
+    match action {
+        Action::Save(path) => match save(&path, &response.body) {
+            Ok(()) => crate::pr_info!("http: saved {} bytes from {} to {}", response.body.len(), url, path),
+            Err(e) => crate::pr_warn!("http: could not save {}: {:?}", path, e),
+        },
+        Action::SetClock => match response.date().map(|time| crate::time::set_utc(&time).map(|()| time)) {
+            Some(Ok(time)) => crate::pr_info!("http: clock set to {} from {}", time, url),
+            Some(Err(e)) => crate::pr_warn!("http: could not set the clock: {}", e),
+            None => crate::pr_warn!("http: {} sent no date", url),
+        },
+        Action::Discard => {}
+    }
+}
+
+// Write a file, making the directories it goes in
+fn save(path: &str, data: &[u8]) -> Result<(), crate::fs::FileSystemError> {
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    let mut directory = String::new();
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+    for component in components.iter().take(components.len().saturating_sub(1)) {
+        directory.push('/');
+        directory.push_str(component);
+        let _ = vfs.create_directory_unchecked(&directory);
+    }
+    vfs.write_file_unchecked(path, data)
+}
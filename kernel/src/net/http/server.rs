@@ -0,0 +1,298 @@
+// HTTP server for /proc
+//
+// Every path is looked up under /proc: files are served as plain text and
+// directories as a page of links, so a browser can walk the kernel's
+// status. Only GET and HEAD are answered. Connections stay open between
+// requests unless the client says otherwise.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+use crate::fs::FileType;
+use crate::net::tcp::{self, TcpState};
+use crate::time::clocksource::now_ns;
+use crate::time::DateTime;
+
+use super::{format_date, header};
+
+pub const DEFAULT_PORT: u16 = 80;
+// Connections served at once; more are turned away
+const MAX_CONNECTIONS: usize = 8;
+const IDLE_TIMEOUT_NS: u64 = 10_000_000_000;
+// Most taken from the receive buffer at once, and the longest request head
+const RECEIVE_CHUNK: usize = 4096;
+const MAX_HEAD: usize = 8192;
+
+static HTTPD: Param<bool> = Param::new("httpd", false, "Start the HTTP status server at boot");
+static PORT: Param<i64> = Param::new("httpd.port", DEFAULT_PORT as i64, "Port the HTTP status server listens on");
+
+struct Connection {
+    conn: u64,
+    address: String,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    sent: usize,
+    last_activity: u64,
+    // Close once the output has gone
+    closing: bool,
+}
+
+struct Server {
+    port: u16,
+    connections: Vec<Connection>,
+    requests: u64,
+}
+
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+pub struct Status {
+    pub port: u16,
+    /// Addresses of the connected clients
+    pub connections: Vec<String>,
+    pub requests: u64,
+}
+
+/// Start the server if the command line asks for it
+pub fn init() {
+    if !HTTPD.get() {
+        return;
+    }
+    let port = PORT.get();
+    let Ok(port) = u16::try_from(port) else {
+        crate::pr_warn!("httpd: {} is not a port", port);
+        return;
+    };
+    match start(port) {
+        Ok(()) => crate::pr_info!("httpd: listening on port {}", port),
+        Err(e) => crate::pr_warn!("httpd: {}", e),
+    }
+}
+
+pub fn start(port: u16) -> Result<(), &'static str> {
+    let mut server = SERVER.lock();
+    if server.is_some() {
+        return Err("already running");
+    }
+    tcp::listen(port)?;
+    *server = Some(Server { port, connections: Vec::new(), requests: 0 });
+    Ok(())
+}
+
+/// Stop listening and close every connection
+pub fn stop() -> Result<(), &'static str> {
+    let server = SERVER.lock().take().ok_or("not running")?;
+    tcp::unlisten(server.port);
+    for connection in server.connections {
+        let _ = tcp::close(connection.conn);
+    }
+    Ok(())
+}
+
+pub fn status() -> Option<Status> {
+    let server = SERVER.lock();
+    let server = server.as_ref()?;
+    Some(Status {
+        port: server.port,
+        connections: server.connections.iter().map(|connection| connection.address.clone()).collect(),
+        requests: server.requests,
+    })
+}
+
+pub(super) fn poll() {
+    let mut server = SERVER.lock();
+    let Some(server) = server.as_mut() else { return };
+    while let Some(conn) = tcp::accept(server.port) {
+        if server.connections.len() >= MAX_CONNECTIONS {
+            let _ = tcp::close(conn);
+            continue;
+        }
+        let address = match tcp::peer(conn) {
+            Some((address, port)) => format!("{}:{}", address, port),
+            None => String::from("?"),
+        };
+        server.connections.push(Connection {
+            conn,
+            address,
+            input: Vec::new(),
+            output: Vec::new(),
+            sent: 0,
+            last_activity: now_ns(),
+            closing: false,
+        });
+    }
+
+    let now = now_ns();
+    let mut requests = 0;
+    server.connections.retain_mut(|connection| {
+        let keep = connection.poll(now, &mut requests);
+        if !keep {
+            let _ = tcp::close(connection.conn);
+        }
+        keep
+    });
+    server.requests += requests;
+}
+
+impl Connection {
+    // Take in requests and send out responses, false once the connection
+    // is done with
+    fn poll(&mut self, now: u64, requests: &mut u64) -> bool {
+        if !matches!(tcp::state(self.conn), Some(TcpState::Established)) {
+            return false;
+        }
+        if let Ok(data) = tcp::recv(self.conn, RECEIVE_CHUNK) {
+            if !data.is_empty() {
+                self.input.extend_from_slice(&data);
+                self.last_activity = now;
+            }
+        }
+        // One request at a time, whose answer goes out before the next is
+        // read
+        while !self.closing && self.sent == self.output.len() {
+            let Some(end) = self.input.windows(4).position(|window| window == b"\r\n\r\n") else {
+                if self.input.len() > MAX_HEAD {
+                    self.respond(431, "Request Header Fields Too Large", &[], b"Request head too long\n", true);
+                }
+                break;
+            };
+            let head: Vec<u8> = self.input.drain(..end + 4).collect();
+            self.request(&head[..end]);
+            *requests += 1;
+        }
+        if self.sent < self.output.len() {
+            let window = tcp::send_window(self.conn).min(self.output.len() - self.sent);
+            if window > 0 && tcp::send(self.conn, &self.output[self.sent..self.sent + window]).is_ok() {
+                self.sent += window;
+                self.last_activity = now;
+            }
+        }
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+            if self.closing {
+                return false;
+            }
+        }
+        now.saturating_sub(self.last_activity) < IDLE_TIMEOUT_NS
+    }
+
+    fn request(&mut self, head: &[u8]) {
+        let Ok(head) = core::str::from_utf8(head) else {
+            return self.respond(400, "Bad Request", &[], b"Bad request\n", true);
+        };
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version)) =
+            (request_line.next(), request_line.next(), request_line.next())
+        else {
+            return self.respond(400, "Bad Request", &[], b"Bad request\n", true);
+        };
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let connection = header(&headers, "Connection").unwrap_or_default().to_ascii_lowercase();
+        let close = match version {
+            "HTTP/1.1" => connection.contains("close"),
+            "HTTP/1.0" => !connection.contains("keep-alive"),
+            _ => return self.respond(505, "HTTP Version Not Supported", &[], b"HTTP/1 only\n", true),
+        };
+        // Requests here have no body; one that comes anyway cannot be
+        // skipped reliably
+        let has_body = header(&headers, "Content-Length").is_some_and(|length| length != "0")
+            || header(&headers, "Transfer-Encoding").is_some();
+        if method != "GET" && method != "HEAD" {
+            let allow = [("Allow", "GET, HEAD")];
+            return self.respond(405, "Method Not Allowed", &allow, b"Only GET and HEAD\n", true);
+        }
+        if has_body {
+            return self.respond(400, "Bad Request", &[], b"Unexpected request body\n", true);
+        }
+
+        let path = target.split(['?', '#']).next().unwrap_or_default();
+        if !path.starts_with('/') || path.split('/').any(|segment| segment == "..") {
+            return self.respond(400, "Bad Request", &[], b"Bad path\n", close);
+        }
+        let (content_type, body) = match page(path) {
+            Some(page) => page,
+            None => return self.respond(404, "Not Found", &[], b"Not found\n", close),
+        };
+        let content_type = [("Content-Type", content_type)];
+        if method == "HEAD" {
+            let length = body.len().to_string();
+            let headers = [content_type[0], ("Content-Length", length.as_str())];
+            self.respond_head(200, "OK", &headers, close);
+        } else {
+            self.respond(200, "OK", &content_type, &body, close);
+        }
+    }
+
+    fn respond(&mut self, status: u16, reason: &str, headers: &[(&str, &str)], body: &[u8], close: bool) {
+        let length = body.len().to_string();
+        let headers = [headers, &[("Content-Length", length.as_str())]].concat();
+        self.respond_head(status, reason, &headers, close);
+        self.output.extend_from_slice(body);
+    }
+
+    // A response's status line and headers; a HEAD response ends there
+    fn respond_head(&mut self, status: u16, reason: &str, headers: &[(&str, &str)], close: bool) {
+        let now = DateTime::from_unix((crate::time::realtime_ns() / 1_000_000_000) as i64, 0);
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason);
+        head.push_str(&format!("Date: {}\r\nServer: ReactOS-Rust\r\n", format_date(&now)));
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if close {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        self.output.extend_from_slice(head.as_bytes());
+        self.closing = close;
+    }
+}
+
+// What a path shows: a /proc file, or a listing of a /proc directory
+fn page(path: &str) -> Option<(&'static str, Vec<u8>)> {
+    let path = path.trim_end_matches('/');
+    let proc_path = format!("/proc{}", path);
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    if let Ok(mut entries) = vfs.list_directory_unchecked(&proc_path) {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let title = escape(if path.is_empty() { "/" } else { path });
+        let mut html =
+            format!("<!DOCTYPE html>\n<html><head><title>{0}</title></head><body>\n<h1>{0}</h1>\n<ul>\n", title);
+        if !path.is_empty() {
+            let parent = &path[..path.rfind('/').unwrap_or(0)];
+            html.push_str(&format!("<li><a href=\"{}/\">..</a></li>\n", escape(parent)));
+        }
+        for entry in entries {
+            let slash = if matches!(entry.file_type, FileType::Directory) { "/" } else { "" };
+            let name = escape(&entry.name);
+            html.push_str(&format!("<li><a href=\"{}/{}{}\">{}{}</a></li>\n", escape(path), name, slash, name, slash));
+        }
+        html.push_str("</ul>\n</body></html>\n");
+        return Some(("text/html; charset=utf-8", html.into_bytes()));
+    }
+    let data = vfs.read_file_unchecked(&proc_path).ok()?;
+    Some(("text/plain; charset=utf-8", data))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,5 +1,245 @@
 // Network Interface Management
+//
+// Each NIC driver registers its controller here under a name, eth0, usb0
+// and so on, and unregisters it when the device goes. Frames go out on the
+// first interface registered, the primary one, whose MAC address is also
+// the stack's. `poll` takes what every interface has received and hands it
+// to the stack.
+//
+// The stack asks for checksum offload on every TCP segment, and sends
+// segments of many MSS when the primary interface does TSO. `transmit`
+// finishes in software whatever the interface cannot.
+//
+// A NIC with several queue pairs has its received flows spread over them by
+// RSS, and each receive queue is polled on its own, with its own budget, as
+// Linux's NAPI polls. Each queue belongs to a CPU, the queues shared out in
+// turn; frames are sent on the sending CPU's queue. Until CPUs poll their
+// own queues, the main loop polls them all. An idle queue can be left alone
+// for a while between polls, as an adapter moderates its interrupts, so a
+// busy link costs fewer polls at the price of some latency.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::buffer::{Offloads, PacketBuffer};
+use super::ethernet::{self, EthernetController, EthernetFrame, MacAddress};
+use super::rss::RssConfig;
+use crate::time::clocksource::now_ns;
+
+// Frames taken from each queue per poll, so one busy queue cannot hold the
+// main loop
+const POLL_BUDGET: usize = 32;
+
+/// A queue pair's counters, and the CPU it belongs to
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    pub cpu: u32,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+    /// Polls of the receive queue, and those that used the whole budget
+    pub polls: u64,
+    pub busy_polls: u64,
+}
+
+struct Queue {
+    stats: QueueStats,
+    // When an idle receive queue is next polled
+    next_poll_ns: u64,
+}
+
+struct Interface {
+    name: String,
+    controller: Box<dyn EthernetController + Send>,
+    queues: Vec<Queue>,
+    rss: Option<RssConfig>,
+    // How long an idle receive queue is left between polls
+    moderation_us: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub mac: MacAddress,
+    pub link_up: bool,
+}
+
+/// An interface's queues and what spreads work over them
+#[derive(Debug, Clone)]
+pub struct QueueInfo {
+    pub offloads: Offloads,
+    pub queues: Vec<QueueStats>,
+    pub rss: Option<RssConfig>,
+    pub moderation_us: u32,
+}
+
+static INTERFACES: Mutex<Vec<Interface>> = Mutex::new(Vec::new());
 
 pub fn init() {
     crate::serial_println!("Network interfaces initialized");
-}
\ No newline at end of file
+}
+
+/// Add an interface, named `prefix` and the first number free; returns the
+/// name
+pub fn register(prefix: &str, mut controller: Box<dyn EthernetController + Send>) -> String {
+    let cpus = crate::smp::SMP_MANAGER.lock().online_cpu_count().max(1);
+    let count = controller.queues().max(1);
+    let queues = (0..count)
+        .map(|index| Queue { stats: QueueStats { cpu: index as u32 % cpus, ..QueueStats::default() }, next_poll_ns: 0 })
+        .collect();
+    let rss = (count > 1).then(|| RssConfig::spread(count));
+    let rss = rss.filter(|config| match controller.set_rss(config) {
+        Ok(()) => true,
+        Err(e) => {
+            crate::serial_println!("net: no RSS: {}", e);
+            false
+        }
+    });
+
+    let mut interfaces = INTERFACES.lock();
+    let name = (0..)
+        .map(|number| format!("{}{}", prefix, number))
+        .find(|name| interfaces.iter().all(|interface| interface.name != *name))
+        .unwrap();
+    crate::serial_println!("net: {} is {}, {} queues", name, controller.get_mac_address(), count);
+    interfaces.push(Interface { name: name.clone(), controller, queues, rss, moderation_us: 0 });
+    name
+}
+
+pub fn unregister(name: &str) {
+    INTERFACES.lock().retain(|interface| interface.name != name);
+}
+
+pub fn interfaces() -> Vec<InterfaceInfo> {
+    INTERFACES
+        .lock()
+        .iter()
+        .map(|interface| InterfaceInfo {
+            name: interface.name.clone(),
+            mac: interface.controller.get_mac_address(),
+            link_up: interface.controller.get_link_status(),
+        })
+        .collect()
+}
+
+/// The primary interface's MAC address
+pub fn mac_address() -> Option<MacAddress> {
+    INTERFACES.lock().first().map(|interface| interface.controller.get_mac_address())
+}
+
+/// Send a frame on the primary interface
+pub fn send_frame(frame: &EthernetFrame) -> Result<(), &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces.first_mut().ok_or("no network interface")?;
+    if !interface.controller.get_link_status() {
+        return Err("link down");
+    }
+    interface.controller.send_frame(frame)
+}
+
+/// An interface's queues, with their counters
+pub fn queue_info(name: &str) -> Option<QueueInfo> {
+    let interfaces = INTERFACES.lock();
+    let interface = interfaces.iter().find(|interface| interface.name == name)?;
+    Some(QueueInfo {
+        offloads: interface.controller.offloads(),
+        queues: interface.queues.iter().map(|queue| queue.stats).collect(),
+        rss: interface.rss.clone(),
+        moderation_us: interface.moderation_us,
+    })
+}
+
+/// Leave an idle receive queue `usecs` between polls; 0 polls it every time
+pub fn set_moderation(name: &str, usecs: u32) -> Result<(), &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces.iter_mut().find(|interface| interface.name == name).ok_or("no such interface")?;
+    interface.moderation_us = usecs;
+    for queue in &mut interface.queues {
+        queue.next_poll_ns = 0;
+    }
+    Ok(())
+}
+
+/// Spread received flows over the first `queues` queues
+pub fn set_rss(name: &str, queues: usize) -> Result<(), &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces.iter_mut().find(|interface| interface.name == name).ok_or("no such interface")?;
+    if queues == 0 || queues > interface.queues.len() {
+        return Err("no such queue");
+    }
+    let config = RssConfig::spread(queues);
+    interface.controller.set_rss(&config)?;
+    interface.rss = Some(config);
+    Ok(())
+}
+
+/// What the primary interface does for the stack
+pub fn offloads() -> Offloads {
+    INTERFACES.lock().first().map_or(Offloads::empty(), |interface| interface.controller.offloads())
+}
+
+/// Send a packet on the primary interface, cutting it into segments and
+/// finishing its checksum first if the interface cannot
+pub fn transmit(packet: PacketBuffer) -> Result<(), &'static str> {
+    let mut interfaces = INTERFACES.lock();
+    let interface = interfaces.first_mut().ok_or("no network interface")?;
+    if !interface.controller.get_link_status() {
+        return Err("link down");
+    }
+    let offloads = interface.controller.offloads();
+    let packets = if packet.segment_size.is_some() && !offloads.contains(Offloads::TSO) {
+        packet.segment().ok_or("cannot segment packet")?
+    } else {
+        alloc::vec![packet]
+    };
+    // The sending CPU's queue
+    let index = crate::smp::current_cpu_id() as usize % interface.queues.len();
+    for mut packet in packets {
+        if !offloads.contains(Offloads::CHECKSUM) {
+            packet.finish_checksum().ok_or("out of memory for a packet")?;
+        }
+        let length = packet.len();
+        let result = interface.controller.transmit_queue(index, packet);
+        let stats = &mut interface.queues[index].stats;
+        if result.is_err() {
+            stats.tx_errors += 1;
+            return result;
+        }
+        stats.tx_packets += 1;
+        stats.tx_bytes += length as u64;
+    }
+    Ok(())
+}
+
+/// Hand what the interfaces have received to the stack
+pub fn poll() {
+    let now = now_ns();
+    // The stack may answer from within, so it runs without the lock
+    let mut received: Vec<PacketBuffer> = Vec::new();
+    for interface in INTERFACES.lock().iter_mut() {
+        let moderation_ns = interface.moderation_us as u64 * 1000;
+        for (index, queue) in interface.queues.iter_mut().enumerate() {
+            if now < queue.next_poll_ns {
+                continue;
+            }
+            let first = received.len();
+            received.extend((0..POLL_BUDGET).map_while(|_| interface.controller.receive_queue(index)));
+            let count = received.len() - first;
+            queue.stats.polls += 1;
+            queue.stats.rx_packets += count as u64;
+            queue.stats.rx_bytes += received[first..].iter().map(PacketBuffer::len).sum::<usize>() as u64;
+            if count == POLL_BUDGET {
+                queue.stats.busy_polls += 1;
+            }
+            queue.next_poll_ns = if count == 0 { now + moderation_ns } else { 0 };
+        }
+    }
+    for packet in received {
+        ethernet::process_packet(packet);
+    }
+}
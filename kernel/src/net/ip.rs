@@ -1,6 +1,7 @@
 // IP (Internet Protocol) Layer Implementation
 use alloc::vec::Vec;
 use core::fmt;
+use super::buffer::PacketBuffer;
 
 // IP protocol numbers
 pub const IP_PROTO_ICMP: u8 = 1;
@@ -330,13 +331,20 @@ pub fn process_ip_packet(data: &[u8]) {
 
 // Send IP packet
 pub fn send_ip_packet(packet: IpPacket) -> Result<(), &'static str> {
-    use super::ethernet::{EthernetFrame, ETHERTYPE_IPV4};
+    let buffer = PacketBuffer::from_bytes(&packet.payload).ok_or("out of memory for a packet")?;
+    transmit(buffer, &packet.header)
+}
+
+/// Send `packet`, holding what follows the IPv4 header, behind `header`,
+/// pushing the headers in front of it
+pub fn transmit(mut packet: PacketBuffer, header: &Ipv4Header) -> Result<(), &'static str> {
+    use super::ethernet::{ETHERTYPE_IPV4, ETH_HEADER_SIZE};
     use super::arp;
     
     // Resolve destination MAC address
-    let dst_mac = if packet.header.dst_addr.is_broadcast() {
+    let dst_mac = if header.dst_addr.is_broadcast() {
         super::ethernet::MacAddress::BROADCAST
-    } else if let Some(mac) = arp::resolve(packet.header.dst_addr) {
+    } else if let Some(mac) = arp::resolve(header.dst_addr) {
         mac
     } else {
         return Err("ARP resolution failed");
@@ -344,20 +352,21 @@ pub fn send_ip_packet(packet: IpPacket) -> Result<(), &'static str> {
     
     let src_mac = get_our_mac();
     
-    // Create Ethernet frame
-    let frame = EthernetFrame::new(
-        dst_mac,
-        src_mac,
-        ETHERTYPE_IPV4,
-        packet.to_bytes(),
-    );
+    let header_bytes = unsafe {
+        core::slice::from_raw_parts(header as *const _ as *const u8, core::mem::size_of::<Ipv4Header>())
+    };
+    packet.push(header_bytes.len()).ok_or("out of memory for a packet")?.copy_from_slice(header_bytes);
+    
+    let ethernet = packet.push(ETH_HEADER_SIZE).ok_or("out of memory for a packet")?;
+    ethernet[0..6].copy_from_slice(dst_mac.as_bytes());
+    ethernet[6..12].copy_from_slice(src_mac.as_bytes());
+    ethernet[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
     
     // Send through network interface
-    crate::serial_println!("Sending IP packet to {}", packet.header.dst_addr);
-    super::update_stats_sent(frame.len());
+    crate::serial_println!("Sending IP packet to {}", header.dst_addr);
+    super::update_stats_sent(packet.len());
     
-    // interface::send_frame(&frame)?;
-    Ok(())
+    super::interface::transmit(packet)
 }
 
 // Helper functions
@@ -367,5 +376,5 @@ fn is_our_ip(ip: &Ipv4Address) -> bool {
 }
 
 fn get_our_mac() -> super::ethernet::MacAddress {
-    super::ethernet::MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+    super::ethernet::get_mac_address()
 }
\ No newline at end of file
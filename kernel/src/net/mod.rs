@@ -6,11 +6,17 @@ pub mod icmp;
 pub mod udp;
 pub mod tcp;
 pub mod socket;
+pub mod stream;
 pub mod dhcp;
 pub mod dns;
 pub mod interface;
 pub mod buffer;
+pub mod rss;
+pub mod e1000;
+pub mod virtio_net;
 pub mod wireless;
+pub mod tls;
+pub mod http;
 
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -0,0 +1,110 @@
+// Receive-Side Scaling
+//
+// A NIC with several receive queues spreads flows across them by hashing
+// each frame's addresses and ports with the Toeplitz hash and a secret key,
+// and looking the hash up in an indirection table of queues. Every frame of
+// a flow lands on the same queue, so on the CPU that polls it, in order.
+// The table decides how flows are shared out: `spread` gives each queue an
+// equal share.
+
+use alloc::vec::Vec;
+
+use super::ethernet::{ETHERTYPE_IPV4, ETH_HEADER_SIZE};
+use super::ip::{IP_PROTO_TCP, IP_PROTO_UDP};
+
+pub const KEY_SIZE: usize = 40;
+
+/// Entries in the indirection table
+pub const TABLE_SIZE: usize = 128;
+
+/// The key Microsoft's RSS specification verifies hashes with, which most
+/// NICs and drivers start from
+pub const DEFAULT_KEY: [u8; KEY_SIZE] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0, 0xd0, 0xca, 0x2b,
+    0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac,
+    0x01, 0xfa,
+];
+
+bitflags::bitflags! {
+    /// What goes into the hash of each kind of frame
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct HashTypes: u32 {
+        /// Source and destination addresses, for IPv4 frames not covered below
+        const IPV4 = 1 << 0;
+        /// The addresses and ports of TCP segments
+        const TCP_IPV4 = 1 << 1;
+        /// The addresses and ports of UDP datagrams
+        const UDP_IPV4 = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RssConfig {
+    pub key: [u8; KEY_SIZE],
+    pub hash_types: HashTypes,
+    /// The queue for each value of the hash's low bits
+    pub table: Vec<u16>,
+}
+
+impl RssConfig {
+    /// Flows shared equally among `queues` queues
+    pub fn spread(queues: usize) -> RssConfig {
+        let queues = queues.max(1);
+        RssConfig {
+            key: DEFAULT_KEY,
+            hash_types: HashTypes::all(),
+            table: (0..TABLE_SIZE).map(|entry| (entry % queues) as u16).collect(),
+        }
+    }
+
+    /// The queue a frame with `hash` goes to
+    pub fn queue(&self, hash: u32) -> u16 {
+        self.table.get(hash as usize % self.table.len().max(1)).copied().unwrap_or(0)
+    }
+
+    /// The queues the table uses, one more than the highest
+    pub fn queues(&self) -> usize {
+        self.table.iter().max().map_or(1, |&queue| queue as usize + 1)
+    }
+}
+
+/// The Toeplitz hash of `input` under `key`
+pub fn toeplitz(key: &[u8; KEY_SIZE], input: &[u8]) -> u32 {
+    let mut hash = 0u32;
+    // The 32 bits of the key lined up with the input bit being hashed
+    let mut window = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+    for (index, &byte) in input.iter().enumerate() {
+        let next = key.get(index + 4).copied().unwrap_or(0);
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = (window << 1) | ((next >> (7 - bit)) & 1) as u32;
+        }
+    }
+    hash
+}
+
+/// The hash of an Ethernet frame, as a NIC would compute it for its queue;
+/// None for frames the hash types leave out, which go to queue 0
+pub fn frame_hash(config: &RssConfig, frame: &[u8]) -> Option<u32> {
+    if frame.len() < ETH_HEADER_SIZE + 20 || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let ip = &frame[ETH_HEADER_SIZE..];
+    let header_len = (ip[0] & 0x0F) as usize * 4;
+    let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3FFF != 0;
+    let mut input = Vec::with_capacity(12);
+    input.extend_from_slice(&ip[12..20]);
+    let ports = match ip[9] {
+        IP_PROTO_TCP => config.hash_types.contains(HashTypes::TCP_IPV4),
+        IP_PROTO_UDP => config.hash_types.contains(HashTypes::UDP_IPV4),
+        _ => false,
+    };
+    if ports && !fragmented && ip.len() >= header_len + 4 {
+        input.extend_from_slice(&ip[header_len..header_len + 4]);
+    } else if !config.hash_types.contains(HashTypes::IPV4) {
+        return None;
+    }
+    Some(toeplitz(&config.key, &input))
+}
@@ -1,5 +1,5 @@
 // Socket API Implementation
-use super::ip::Ipv4Address;
+use super::ip::{IpAddress, Ipv4Address};
 use super::tcp::{TcpSocket, TcpControlBlock};
 use super::udp::UdpSocket;
 use alloc::collections::BTreeMap;
@@ -79,6 +79,8 @@ pub struct Socket {
     pub remote_addr: Option<SocketAddr>,
     pub options: SocketOptions,
     pub implementation: Option<SocketImpl>,
+    /// Network namespace of the process that created the socket
+    pub netns: u32,
 }
 
 impl Socket {
@@ -93,6 +95,16 @@ impl Socket {
             remote_addr: None,
             options: SocketOptions::default(),
             implementation: None,
+            netns: current_netns(),
+        }
+    }
+    
+    // A network namespace other than the initial one reaches only loopback
+    fn check_reachable(&self, ip: Ipv4Address) -> Result<(), &'static str> {
+        if self.netns == 0 || ip.is_loopback() || ip == Ipv4Address::UNSPECIFIED {
+            Ok(())
+        } else {
+            Err("Network unreachable")
         }
     }
     
@@ -100,9 +112,10 @@ impl Socket {
         if self.state != SocketState::Unbound {
             return Err("Socket already bound");
         }
+        self.check_reachable(addr.ip)?;
         
         // Check if address is already in use
-        if !self.options.reuse_addr && is_port_in_use(addr.port, self.socket_type) {
+        if !self.options.reuse_addr && is_port_in_use(self.netns, addr.port, self.socket_type) {
             return Err("Address already in use");
         }
         
@@ -125,7 +138,7 @@ impl Socket {
         }
         
         // Register socket
-        register_socket(self.id, addr.port, self.socket_type);
+        register_socket(self.netns, self.id, addr.port, self.socket_type);
         
         Ok(())
     }
@@ -152,11 +165,12 @@ impl Socket {
         if self.socket_type != SocketType::Stream {
             return Err("Only stream sockets can connect");
         }
+        self.check_reachable(addr.ip)?;
         
         // Auto-bind if not bound
         if self.state == SocketState::Unbound {
-            let local_port = allocate_ephemeral_port();
-            let local_addr = SocketAddr::new(get_local_ip(), local_port);
+            let local_port = allocate_ephemeral_port(self.netns);
+            let local_addr = SocketAddr::new(get_local_ip(self.netns), local_port);
             self.bind(local_addr)?;
         }
         
@@ -225,11 +239,12 @@ impl Socket {
         if self.socket_type != SocketType::Datagram {
             return Err("send_to only works with datagram sockets");
         }
+        self.check_reachable(addr.ip)?;
         
         // Auto-bind if not bound
         if self.state == SocketState::Unbound {
-            let local_port = allocate_ephemeral_port();
-            let local_addr = SocketAddr::new(get_local_ip(), local_port);
+            let local_port = allocate_ephemeral_port(self.netns);
+            let local_addr = SocketAddr::new(get_local_ip(self.netns), local_port);
             self.bind(local_addr)?;
         }
         
@@ -271,7 +286,7 @@ impl Socket {
         
         // Unregister socket
         if let Some(addr) = self.local_addr {
-            unregister_socket(self.id, addr.port);
+            unregister_socket(self.netns, self.id, addr.port);
         }
         
         Ok(())
@@ -311,16 +326,21 @@ pub enum SocketOption {
     SendTimeout(Option<u64>),
 }
 
-// Socket registry
+// Socket registry, keyed by network namespace and port
 lazy_static! {
-    static ref SOCKET_REGISTRY: Mutex<BTreeMap<u16, Vec<(u32, SocketType)>>> = 
+    static ref SOCKET_REGISTRY: Mutex<BTreeMap<(u32, u16), Vec<(u32, SocketType)>>> = 
         Mutex::new(BTreeMap::new());
     static ref EPHEMERAL_PORT_COUNTER: AtomicU32 = AtomicU32::new(49152);
 }
 
-fn is_port_in_use(port: u16, socket_type: SocketType) -> bool {
+fn current_netns() -> u32 {
+    let pid = crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0);
+    crate::container::nsproxy::net_namespace(pid)
+}
+
+fn is_port_in_use(netns: u32, port: u16, socket_type: SocketType) -> bool {
     let registry = SOCKET_REGISTRY.lock();
-    if let Some(sockets) = registry.get(&port) {
+    if let Some(sockets) = registry.get(&(netns, port)) {
         for (_, stype) in sockets {
             if *stype == socket_type || socket_type == SocketType::Stream {
                 return true;
@@ -330,22 +350,22 @@ fn is_port_in_use(port: u16, socket_type: SocketType) -> bool {
     false
 }
 
-fn register_socket(id: u32, port: u16, socket_type: SocketType) {
+fn register_socket(netns: u32, id: u32, port: u16, socket_type: SocketType) {
     let mut registry = SOCKET_REGISTRY.lock();
-    registry.entry(port).or_insert_with(Vec::new).push((id, socket_type));
+    registry.entry((netns, port)).or_insert_with(Vec::new).push((id, socket_type));
 }
 
-fn unregister_socket(id: u32, port: u16) {
+fn unregister_socket(netns: u32, id: u32, port: u16) {
     let mut registry = SOCKET_REGISTRY.lock();
-    if let Some(sockets) = registry.get_mut(&port) {
+    if let Some(sockets) = registry.get_mut(&(netns, port)) {
         sockets.retain(|(sid, _)| *sid != id);
         if sockets.is_empty() {
-            registry.remove(&port);
+            registry.remove(&(netns, port));
         }
     }
 }
 
-fn allocate_ephemeral_port() -> u16 {
+fn allocate_ephemeral_port(netns: u32) -> u16 {
     // Ephemeral port range: 49152-65535
     loop {
         let port = EPHEMERAL_PORT_COUNTER.fetch_add(1, Ordering::SeqCst) as u16;
@@ -354,7 +374,7 @@ fn allocate_ephemeral_port() -> u16 {
             continue;
         }
         
-        if !is_port_in_use(port, SocketType::Stream) {
+        if !is_port_in_use(netns, port, SocketType::Stream) {
             return port;
         }
         
@@ -364,7 +384,15 @@ fn allocate_ephemeral_port() -> u16 {
     }
 }
 
-fn get_local_ip() -> Ipv4Address {
+/// A free local port for a connection the kernel opens itself
+pub fn ephemeral_port() -> u16 {
+    allocate_ephemeral_port(0)
+}
+
+fn get_local_ip(netns: u32) -> Ipv4Address {
+    if netns != 0 {
+        return Ipv4Address::LOOPBACK;
+    }
     // In real implementation, would get from network interface
     Ipv4Address::new(192, 168, 1, 100)
 }
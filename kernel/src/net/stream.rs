@@ -0,0 +1,184 @@
+//! TCP streams as process descriptors
+//!
+//! `listen` and `connect` give a process a descriptor on the TCP stack, as
+//! the kernel's own servers use it: a listening port, or a connection by
+//! its key. `accept` takes a connection off a listening port, and `read`
+//! and `write` on a connection receive and send.
+//!
+//! Nothing here blocks. Calls that cannot go ahead yet fail with EAGAIN: an
+//! accept with no connection waiting, a read with nothing received, a write
+//! with the send window shut or a handshake still under way. The io_uring
+//! operations retry them until they can. A read after the peer has closed
+//! its side returns 0.
+//!
+//! Processes in a network namespace of their own cannot reach the stack
+//! yet, and ports below 1024 are an administrator's to listen on.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::net::ip::Ipv4Address;
+use crate::net::tcp::{self, TcpState};
+use crate::sync::Mutex;
+use crate::syscall::{EACCES, EADDRINUSE, EAGAIN, EBADF, ECONNRESET, EINVAL, EMFILE, ENOTCONN, EPERM};
+
+// Per-process limit on open streams
+const MAX_HANDLES: usize = 64;
+
+// Clear of /dev/kvm's handles, io_uring's, the serial ports' and files
+const FIRST_FD: usize = 768;
+
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Listener(u16),
+    Connection(u64),
+}
+
+// Stream by (process, fd)
+static HANDLES: Mutex<BTreeMap<(u32, usize), Stream>> = Mutex::new(BTreeMap::new());
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+/// Whether `fd` is a stream the caller has open
+pub fn owns(fd: usize) -> bool {
+    HANDLES.lock().contains_key(&(current_pid(), fd))
+}
+
+fn stream_of(fd: usize) -> Result<Stream, usize> {
+    HANDLES.lock().get(&(current_pid(), fd)).copied().ok_or(EBADF)
+}
+
+// A descriptor for `stream` in `pid`'s table
+fn insert(pid: u32, stream: Stream) -> Result<usize, usize> {
+    let mut handles = HANDLES.lock();
+    let used: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_HANDLES {
+        return Err(EMFILE);
+    }
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    handles.insert((pid, fd), stream);
+    Ok(fd)
+}
+
+fn check_namespace(pid: u32) -> Result<(), usize> {
+    if crate::container::nsproxy::namespaces(pid).net != 0 {
+        return Err(EPERM);
+    }
+    Ok(())
+}
+
+/// Listen on `port`
+pub fn listen(port: u16) -> Result<usize, usize> {
+    let pid = current_pid();
+    check_namespace(pid)?;
+    if port == 0 {
+        return Err(EINVAL);
+    }
+    if port < FIRST_UNPRIVILEGED_PORT && !crate::security::accounts::caller_is_admin() {
+        return Err(EACCES);
+    }
+    tcp::listen(port).map_err(|_| EADDRINUSE)?;
+    insert(pid, Stream::Listener(port)).inspect_err(|_| tcp::unlisten(port))
+}
+
+/// Start connecting to `address`, given as a number in host order, and
+/// `port`; writes fail with EAGAIN until the handshake is done
+pub fn connect(address: u32, port: u16) -> Result<usize, usize> {
+    let pid = current_pid();
+    check_namespace(pid)?;
+    if port == 0 {
+        return Err(EINVAL);
+    }
+    let remote = Ipv4Address::from_u32(address);
+    let conn = tcp::connect(crate::net::socket::ephemeral_port(), remote, port).map_err(|_| ENOTCONN)?;
+    insert(pid, Stream::Connection(conn)).inspect_err(|_| {
+        let _ = tcp::close(conn);
+    })
+}
+
+/// A connection waiting on the listening stream `fd`, as a new descriptor
+pub fn accept(fd: usize) -> Result<usize, usize> {
+    let Stream::Listener(port) = stream_of(fd)? else {
+        return Err(EINVAL);
+    };
+    let conn = tcp::accept(port).ok_or(EAGAIN)?;
+    insert(current_pid(), Stream::Connection(conn)).inspect_err(|_| {
+        let _ = tcp::close(conn);
+    })
+}
+
+fn connection(fd: usize) -> Result<u64, usize> {
+    match stream_of(fd)? {
+        Stream::Connection(conn) => Ok(conn),
+        Stream::Listener(_) => Err(ENOTCONN),
+    }
+}
+
+/// Take what has been received on `fd`
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, usize> {
+    let conn = connection(fd)?;
+    let data = tcp::recv(conn, buffer.len()).map_err(|_| ECONNRESET)?;
+    if !data.is_empty() {
+        buffer[..data.len()].copy_from_slice(&data);
+        return Ok(data.len());
+    }
+    match tcp::state(conn) {
+        // Still open from the peer's side
+        Some(TcpState::SynSent | TcpState::SynReceived | TcpState::Established)
+        | Some(TcpState::FinWait1 | TcpState::FinWait2) => Err(EAGAIN),
+        // The peer has closed its side
+        Some(_) => Ok(0),
+        None => Err(ECONNRESET),
+    }
+}
+
+/// Send what the window has room for of `data`
+pub fn write(fd: usize, data: &[u8]) -> Result<usize, usize> {
+    let conn = connection(fd)?;
+    match tcp::state(conn) {
+        Some(TcpState::SynSent | TcpState::SynReceived) => return Err(EAGAIN),
+        Some(TcpState::Established | TcpState::CloseWait) => {}
+        Some(_) => return Err(ENOTCONN),
+        None => return Err(ECONNRESET),
+    }
+    let count = tcp::send_window(conn).min(data.len());
+    if count == 0 && !data.is_empty() {
+        return Err(EAGAIN);
+    }
+    tcp::send(conn, &data[..count]).map_err(|_| ECONNRESET)?;
+    Ok(count)
+}
+
+fn release(stream: Stream) {
+    match stream {
+        Stream::Listener(port) => tcp::unlisten(port),
+        Stream::Connection(conn) => {
+            let _ = tcp::close(conn);
+        }
+    }
+}
+
+/// Close a stream; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let Some(stream) = HANDLES.lock().remove(&(current_pid(), fd)) else {
+        return false;
+    };
+    release(stream);
+    true
+}
+
+/// Close every stream an exiting process has open
+pub fn release_process(pid: u32) {
+    let streams: Vec<Stream> = {
+        let mut handles = HANDLES.lock();
+        let fds: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+        fds.into_iter().filter_map(|fd| handles.remove(&(pid, fd))).collect()
+    };
+    for stream in streams {
+        release(stream);
+    }
+}
@@ -1,5 +1,6 @@
 // TCP (Transmission Control Protocol) Implementation
-use super::ip::{IpPacket, Ipv4Address, IP_PROTO_TCP};
+use super::buffer::{pseudo_header_sum, Offloads, PacketBuffer, PartialChecksum};
+use super::ip::{IpPacket, Ipv4Address, Ipv4Header, IP_PROTO_TCP};
 use alloc::vec::Vec;
 use alloc::collections::{BTreeMap, VecDeque};
 use spin::Mutex;
@@ -7,6 +8,7 @@ use lazy_static::lazy_static;
 use core::cmp::{min, max};
 use core::time::Duration;
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
 
 // TCP Header flags
 const TCP_FIN: u8 = 0x01;
@@ -30,6 +32,7 @@ const TCP_OPT_TIMESTAMP: u8 = 8;
 // TCP Constants
 const TCP_MSS_DEFAULT: u16 = 536;
 const TCP_MSS_ETHERNET: u16 = 1460;
+const TCP_TSO_MAX_DATA: usize = 65535 - 20 - 60;  // An IPv4 packet's worth, less the headers
 const TCP_WINDOW_DEFAULT: u16 = 65535;
 const TCP_WINDOW_SCALE_MAX: u8 = 14;
 const TCP_MAX_RETRIES: u32 = 15;
@@ -73,16 +76,17 @@ pub struct TcpHeader {
 }
 
 impl TcpHeader {
+    // The fields are kept in network order already, so their bytes go out as they are
     pub fn to_bytes(&self) -> [u8; 20] {
         let mut bytes = [0u8; 20];
-        bytes[0..2].copy_from_slice(&self.src_port.to_be_bytes());
-        bytes[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
-        bytes[4..8].copy_from_slice(&self.seq_num.to_be_bytes());
-        bytes[8..12].copy_from_slice(&self.ack_num.to_be_bytes());
-        bytes[12..14].copy_from_slice(&self.data_offset_flags.to_be_bytes());
-        bytes[14..16].copy_from_slice(&self.window.to_be_bytes());
-        bytes[16..18].copy_from_slice(&self.checksum.to_be_bytes());
-        bytes[18..20].copy_from_slice(&self.urgent_ptr.to_be_bytes());
+        bytes[0..2].copy_from_slice(&{ self.src_port }.to_ne_bytes());
+        bytes[2..4].copy_from_slice(&{ self.dst_port }.to_ne_bytes());
+        bytes[4..8].copy_from_slice(&{ self.seq_num }.to_ne_bytes());
+        bytes[8..12].copy_from_slice(&{ self.ack_num }.to_ne_bytes());
+        bytes[12..14].copy_from_slice(&{ self.data_offset_flags }.to_ne_bytes());
+        bytes[14..16].copy_from_slice(&{ self.window }.to_ne_bytes());
+        bytes[16..18].copy_from_slice(&{ self.checksum }.to_ne_bytes());
+        bytes[18..20].copy_from_slice(&{ self.urgent_ptr }.to_ne_bytes());
         bytes
     }
     
@@ -140,6 +144,8 @@ pub struct TcpSegment {
     pub header: TcpHeader,
     pub options: Vec<u8>,
     pub data: Vec<u8>,
+    // Data beyond one MSS, which the NIC or `interface::transmit` cuts up
+    pub segment_size: u16,
 }
 
 impl TcpSegment {
@@ -148,6 +154,7 @@ impl TcpSegment {
             header,
             options: Vec::new(),
             data,
+            segment_size: 0,
         }
     }
     
@@ -182,6 +189,7 @@ impl TcpSegment {
             header,
             options,
             data: payload,
+            segment_size: 0,
         })
     }
     
@@ -189,14 +197,7 @@ impl TcpSegment {
         let mut segment = Vec::new();
         
         // Add header
-        segment.extend_from_slice(&self.header.src_port.to_be_bytes());
-        segment.extend_from_slice(&self.header.dst_port.to_be_bytes());
-        segment.extend_from_slice(&self.header.seq_num.to_be_bytes());
-        segment.extend_from_slice(&self.header.ack_num.to_be_bytes());
-        segment.extend_from_slice(&self.header.data_offset_flags.to_be_bytes());
-        segment.extend_from_slice(&self.header.window.to_be_bytes());
-        segment.extend_from_slice(&self.header.checksum.to_be_bytes());
-        segment.extend_from_slice(&self.header.urgent_ptr.to_be_bytes());
+        segment.extend_from_slice(&self.header.to_bytes());
         
         // Add options
         segment.extend_from_slice(&self.options);
@@ -626,13 +627,9 @@ impl TcpControlBlock {
         }
     }
     
+    // Milliseconds on the clocksource timeline
     fn get_timestamp() -> u64 {
-        // In a real OS, this would return system uptime in milliseconds
-        static mut TIMESTAMP: u64 = 0;
-        unsafe {
-            TIMESTAMP += 1;
-            TIMESTAMP
-        }
+        crate::time::clocksource::now_ns() / 1_000_000
     }
     
     // Timer management
@@ -647,19 +644,20 @@ impl TcpControlBlock {
             expires_at,
             retry_count: 0,
         });
+        arm_stack_timer(expires_at);
     }
     
     pub fn cancel_timer(&mut self, timer_type: TcpTimer) {
         self.timers.retain(|t| t.timer_type != timer_type);
     }
     
-    pub fn check_timers(&mut self) -> Vec<TcpTimer> {
+    pub fn check_timers(&mut self) -> Vec<TimerState> {
         let now = Self::get_timestamp();
         let mut expired = Vec::new();
         
         self.timers.retain(|timer| {
             if timer.expires_at <= now {
-                expired.push(timer.timer_type);
+                expired.push(timer.clone());
                 false
             } else {
                 true
@@ -669,6 +667,33 @@ impl TcpControlBlock {
         expired
     }
     
+    // Retransmission timeout (RFC 6298 5.4-5.7): resend the oldest
+    // unacknowledged segment and double the RTO. After TCP_MAX_RETRIES the
+    // connection is given up on.
+    pub fn on_retransmit_timeout(&mut self, retry_count: u32) -> Option<TcpSegment> {
+        let (_, data, _) = self.retransmit_queue.front()?;
+        if retry_count >= TCP_MAX_RETRIES {
+            crate::serial_println!("TCP: no answer from {}:{}, giving up", self.remote_addr, self.remote_port);
+            self.state = TcpState::Closed;
+            self.cancel_all_timers();
+            return None;
+        }
+        let mut segment = TcpSegment::from_bytes(data).ok()?;
+        segment.segment_size = min(self.mss, self.peer_mss);
+        
+        self.retransmissions += 1;
+        self.congestion.on_loss();
+        // Karn's algorithm: no RTT sample from a retransmitted segment
+        self.congestion.rtt_seq = 0;
+        self.congestion.rto = min(self.congestion.rto * 2, TCP_RTO_MAX);
+        self.set_timer(TcpTimer::Retransmission, self.congestion.rto);
+        if let Some(timer) = self.timers.last_mut() {
+            timer.retry_count = retry_count + 1;
+        }
+        
+        Some(segment)
+    }
+    
     // RTT estimation (Jacobson/Karels algorithm)
     pub fn update_rtt(&mut self, measured_rtt: u32) {
         if self.congestion.srtt == 0 {
@@ -943,8 +968,15 @@ impl TcpControlBlock {
         let effective_mss = min(self.mss, self.peer_mss) as usize;
         let max_to_send = min(data.len(), available_window as usize);
         
+        // A NIC doing TSO takes many MSS of data at once and cuts it up itself
+        let max_chunk = if super::interface::offloads().contains(Offloads::TSO) {
+            max(effective_mss, TCP_TSO_MAX_DATA / effective_mss * effective_mss)
+        } else {
+            effective_mss
+        };
+        
         while offset < max_to_send {
-            let chunk_size = min(effective_mss, max_to_send - offset);
+            let chunk_size = min(max_chunk, max_to_send - offset);
             let chunk = data[offset..offset + chunk_size].to_vec();
             
             let options = self.build_options(false, true);
@@ -992,6 +1024,7 @@ impl TcpControlBlock {
             
             let mut segment = TcpSegment::new(header, chunk);
             segment.options = options;
+            segment.segment_size = effective_mss as u16;
             segments.push(segment);
             
             offset += chunk_size;
@@ -1520,6 +1553,69 @@ impl TcpConnection {
 lazy_static! {
     static ref TCP_SOCKETS: Mutex<BTreeMap<u16, TcpSocket>> = Mutex::new(BTreeMap::new());
     static ref TCP_CONNECTIONS: Mutex<BTreeMap<u64, TcpSocket>> = Mutex::new(BTreeMap::new());
+    // Connections made to each listening port, waiting for accept
+    static ref BACKLOG: Mutex<BTreeMap<u16, VecDeque<u64>>> = Mutex::new(BTreeMap::new());
+}
+
+// Every connection's timers share one hrtimer, armed for the earliest
+// deadline (in ms). It fires in interrupt context and only flags the work
+// for poll_timers.
+static TIMERS_DUE: AtomicBool = AtomicBool::new(false);
+static ARMED_AT: AtomicU64 = AtomicU64::new(u64::MAX);
+static ARMED_ID: AtomicU64 = AtomicU64::new(0);
+
+fn arm_stack_timer(expires_at: u64) {
+    if ARMED_AT.fetch_min(expires_at, AtomicOrdering::AcqRel) <= expires_at {
+        return;
+    }
+    let id = crate::time::hrtimer::start(expires_at.saturating_mul(1_000_000), stack_timer_expired, 0);
+    let previous = ARMED_ID.swap(id, AtomicOrdering::AcqRel);
+    if previous != 0 {
+        crate::time::hrtimer::cancel(previous);
+    }
+}
+
+fn stack_timer_expired(_: u64) {
+    ARMED_AT.store(u64::MAX, AtomicOrdering::Release);
+    ARMED_ID.store(0, AtomicOrdering::Release);
+    TIMERS_DUE.store(true, AtomicOrdering::Release);
+}
+
+// Run the timers of every connection that have expired
+pub fn poll_timers() {
+    if !TIMERS_DUE.swap(false, AtomicOrdering::AcqRel) {
+        return;
+    }
+    
+    let mut connections = TCP_CONNECTIONS.lock();
+    let mut next = u64::MAX;
+    for socket in connections.values_mut() {
+        for timer in socket.tcb.check_timers() {
+            match timer.timer_type {
+                TcpTimer::Retransmission => {
+                    if let Some(segment) = socket.tcb.on_retransmit_timeout(timer.retry_count) {
+                        send_tcp_segment(segment, socket.tcb.local_addr, socket.tcb.remote_addr);
+                    }
+                }
+                TcpTimer::DelayedAck => {
+                    let ack = socket.tcb.send_ack();
+                    send_tcp_segment(ack, socket.tcb.local_addr, socket.tcb.remote_addr);
+                }
+                TcpTimer::TimeWait => socket.tcb.state = TcpState::Closed,
+                // Window probes and keep-alives are not sent yet
+                TcpTimer::Persist | TcpTimer::KeepAlive => {}
+            }
+        }
+        for timer in &socket.tcb.timers {
+            next = min(next, timer.expires_at);
+        }
+    }
+    connections.retain(|_, socket| socket.tcb.state != TcpState::Closed);
+    drop(connections);
+    
+    if next != u64::MAX {
+        arm_stack_timer(next);
+    }
 }
 
 // Process incoming TCP segment
@@ -1570,6 +1666,8 @@ pub fn process_tcp_packet(ip_packet: &IpPacket) {
                 // Add to connections table
                 let mut connections = TCP_CONNECTIONS.lock();
                 connections.insert(conn_key, new_socket);
+                drop(connections);
+                BACKLOG.lock().entry(dst_port).or_default().push_back(conn_key);
             }
         }
     } else {
@@ -1579,20 +1677,33 @@ pub fn process_tcp_packet(ip_packet: &IpPacket) {
 }
 
 // Send TCP segment
-fn send_tcp_segment(mut segment: TcpSegment, src_addr: Ipv4Address, dst_addr: Ipv4Address) {
-    // Calculate checksum
-    segment.header.checksum = segment.calculate_checksum(src_addr, dst_addr).to_be();
-    
-    // Create IP packet
-    let ip_packet = IpPacket::new(
-        src_addr,
-        dst_addr,
-        IP_PROTO_TCP,
-        segment.to_bytes(),
-    );
+fn send_tcp_segment(segment: TcpSegment, src_addr: Ipv4Address, dst_addr: Ipv4Address) {
+    let header_len = 20 + segment.options.len();
+    let length = header_len + segment.data.len();
+    
+    // The NIC finishes the checksum, or interface::transmit for one that
+    // cannot; the field holds the pseudo-header's sum meanwhile
+    let mut header = segment.header;
+    header.checksum = pseudo_header_sum(src_addr, dst_addr, IP_PROTO_TCP, length).to_be();
+    
+    let Some(mut packet) = PacketBuffer::from_bytes(&segment.data) else {
+        crate::serial_println!("Failed to send TCP segment: out of memory");
+        return;
+    };
+    let Some(bytes) = packet.push(header_len) else {
+        crate::serial_println!("Failed to send TCP segment: out of memory");
+        return;
+    };
+    bytes[..20].copy_from_slice(&header.to_bytes());
+    bytes[20..].copy_from_slice(&segment.options);
+    packet.checksum = Some(PartialChecksum { start: 0, offset: 16 });
+    if segment.segment_size > 0 && segment.data.len() > segment.segment_size as usize {
+        packet.segment_size = Some(segment.segment_size as usize);
+    }
     
     // Send through IP layer
-    if let Err(e) = super::ip::send_ip_packet(ip_packet) {
+    let ip_header = Ipv4Header::new(src_addr, dst_addr, IP_PROTO_TCP, length);
+    if let Err(e) = super::ip::transmit(packet, &ip_header) {
         crate::serial_println!("Failed to send TCP segment: {}", e);
     }
 }
@@ -1631,6 +1742,31 @@ pub fn listen(port: u16) -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Stop listening on a port; connections already made are left alone
+pub fn unlisten(port: u16) {
+    TCP_SOCKETS.lock().remove(&port);
+    BACKLOG.lock().remove(&port);
+}
+
+/// The oldest connection made to a listening port that has finished its
+/// handshake, to be used as `connect`'s are
+pub fn accept(port: u16) -> Option<u64> {
+    let mut backlog = BACKLOG.lock();
+    let pending = backlog.get_mut(&port)?;
+    let connections = TCP_CONNECTIONS.lock();
+    // Handshakes that were reset or timed out
+    pending.retain(|key| connections.contains_key(key));
+    let index = pending.iter().position(|key| {
+        !matches!(connections.get(key).map(|socket| socket.tcb.state), Some(TcpState::Listen | TcpState::SynReceived))
+    })?;
+    pending.remove(index)
+}
+
+/// The address and port at the other end of a connection
+pub fn peer(conn_key: u64) -> Option<(Ipv4Address, u16)> {
+    TCP_CONNECTIONS.lock().get(&conn_key).map(|socket| (socket.tcb.remote_addr, socket.tcb.remote_port))
+}
+
 pub fn connect(local_port: u16, remote_addr: Ipv4Address, remote_port: u16) -> Result<u64, &'static str> {
     let local_addr = Ipv4Address::new(192, 168, 1, 100);
     let mut socket = TcpSocket::new(local_addr, local_port);
@@ -1676,6 +1812,17 @@ pub fn recv(conn_key: u64, max_len: usize) -> Result<Vec<u8>, &'static str> {
     }
 }
 
+/// Where a connection stands, None once it is gone
+pub fn state(conn_key: u64) -> Option<TcpState> {
+    TCP_CONNECTIONS.lock().get(&conn_key).map(|socket| socket.tcb.state)
+}
+
+/// Bytes `send` can put on the wire right now; anything past this is
+/// buffered but not transmitted
+pub fn send_window(conn_key: u64) -> usize {
+    TCP_CONNECTIONS.lock().get(&conn_key).map_or(0, |socket| socket.tcb.effective_send_window() as usize)
+}
+
 pub fn close(conn_key: u64) -> Result<(), &'static str> {
     let mut connections = TCP_CONNECTIONS.lock();
     
@@ -0,0 +1,713 @@
+//! TLS 1.3 client (RFC 8446)
+//!
+//! Enough TLS for HTTPS: X25519 key exchange, AES-128-GCM or
+//! ChaCha20-Poly1305 with SHA-256, and the server authenticated by a
+//! certificate chain leading to one of the trusted roots, the certificates
+//! in the `tls.certs` directory. There is no session resumption, early
+//! data or client certificate, and no older version of the protocol.
+//!
+//! A `Stream` runs over a TCP connection and is moved on by its owner's
+//! `poll`, like TCP itself; data sent before the handshake is done goes
+//! out once it is.
+
+pub mod x509;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::boot::cmdline::Param;
+use crate::crypto::asymmetric;
+use crate::crypto::hash::SHA256;
+use crate::crypto::kdf::HKDF;
+use crate::crypto::{self, AeadAlgorithm, HashAlgorithm, MacAlgorithm};
+use crate::net::tcp::{self, TcpState};
+use crate::time::clocksource::now_ns;
+
+use x509::{Certificate, Signature};
+
+static CERTS: Param<&str> = Param::new("tls.certs", "/certs", "Directory of trusted root certificates");
+
+// How long a server has to accept the connection and finish the handshake
+const CONNECT_TIMEOUT_NS: u64 = 10_000_000_000;
+const HANDSHAKE_TIMEOUT_NS: u64 = 30_000_000_000;
+// Most taken from the receive buffer at once
+const RECEIVE_CHUNK: usize = 16384;
+// Plaintext in one record, and the most a protected record may add to it
+const MAX_FRAGMENT: usize = 16384;
+const MAX_EXPANSION: usize = 256;
+const MAX_HANDSHAKE_MESSAGE: usize = 65536;
+
+// Record content types
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+// Handshake message types
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const NEW_SESSION_TICKET: u8 = 4;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const CERTIFICATE: u8 = 11;
+const CERTIFICATE_REQUEST: u8 = 13;
+const CERTIFICATE_VERIFY: u8 = 15;
+const FINISHED: u8 = 20;
+const KEY_UPDATE: u8 = 24;
+
+// Extensions
+const SERVER_NAME: u16 = 0;
+const SUPPORTED_GROUPS: u16 = 10;
+const SIGNATURE_ALGORITHMS: u16 = 13;
+const SUPPORTED_VERSIONS: u16 = 43;
+const KEY_SHARE: u16 = 51;
+
+const LEGACY_VERSION: u16 = 0x0303;
+const TLS_1_3: u16 = 0x0304;
+const X25519: u16 = 0x001D;
+const TLS_AES_128_GCM_SHA256: u16 = 0x1301;
+const TLS_CHACHA20_POLY1305_SHA256: u16 = 0x1303;
+
+// Signature schemes; PKCS #1 only in certificates
+const RSA_PKCS1_SHA256: u16 = 0x0401;
+const ECDSA_SECP256R1_SHA256: u16 = 0x0403;
+const RSA_PKCS1_SHA384: u16 = 0x0501;
+const ECDSA_SECP384R1_SHA384: u16 = 0x0503;
+const RSA_PKCS1_SHA512: u16 = 0x0601;
+const RSA_PSS_RSAE_SHA256: u16 = 0x0804;
+const RSA_PSS_RSAE_SHA384: u16 = 0x0805;
+const RSA_PSS_RSAE_SHA512: u16 = 0x0806;
+const ED25519: u16 = 0x0807;
+const SIGNATURE_SCHEMES: [u16; 9] = [
+    ECDSA_SECP256R1_SHA256,
+    ED25519,
+    RSA_PSS_RSAE_SHA256,
+    RSA_PSS_RSAE_SHA384,
+    RSA_PSS_RSAE_SHA512,
+    RSA_PKCS1_SHA256,
+    RSA_PKCS1_SHA384,
+    RSA_PKCS1_SHA512,
+    ECDSA_SECP384R1_SHA384,
+];
+
+// Alerts
+const WARNING: u8 = 1;
+const FATAL: u8 = 2;
+const CLOSE_NOTIFY: u8 = 0;
+
+// A ServerHello with this random is a HelloRetryRequest
+const RETRY_RANDOM: [u8; 32] = [
+    0xCF, 0x21, 0xAD, 0x74, 0xE5, 0x9A, 0x61, 0x11, 0xBE, 0x1D, 0x8C, 0x02, 0x1E, 0x65, 0xB8, 0x91, 0xC2, 0xA2,
+    0x11, 0x16, 0x7A, 0xBB, 0x8C, 0x5E, 0x07, 0x9E, 0x09, 0xE2, 0xC8, 0xA8, 0x33, 0x9C,
+];
+const CERTIFICATE_VERIFY_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify\0";
+
+const TRUNCATED: &str = "TLS message truncated";
+
+// Big-endian fields one after another
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, at: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self.bytes.get(self.at..self.at + length).ok_or(TRUNCATED)?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, &'static str> {
+        let bytes = self.take(3)?;
+        Ok((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    // A vector with a length prefix of one, two or three bytes
+    fn vector(&mut self, prefix: usize) -> Result<&'a [u8], &'static str> {
+        let length = match prefix {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?,
+        };
+        self.take(length)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+}
+
+// Append `data` with a big-endian length prefix of `prefix` bytes
+fn put_vector(out: &mut Vec<u8>, prefix: usize, data: &[u8]) {
+    out.extend_from_slice(&data.len().to_be_bytes()[core::mem::size_of::<usize>() - prefix..]);
+    out.extend_from_slice(data);
+}
+
+fn put_extension(out: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    out.extend_from_slice(&kind.to_be_bytes());
+    put_vector(out, 2, data);
+}
+
+fn handshake_message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = alloc::vec![kind];
+    put_vector(&mut message, 3, body);
+    message
+}
+
+fn hkdf() -> HKDF<SHA256> {
+    HKDF::new(SHA256::new())
+}
+
+fn expand_label(secret: &[u8], label: &str, context: &[u8], length: usize) -> Vec<u8> {
+    let mut info = (length as u16).to_be_bytes().to_vec();
+    put_vector(&mut info, 1, &[b"tls13 ", label.as_bytes()].concat());
+    put_vector(&mut info, 1, context);
+    // Lengths here are far below HKDF's limit
+    hkdf().expand(secret, &info, length).unwrap_or_default()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    crypto::hmac(MacAlgorithm::HmacSHA256, key, data).unwrap_or_default()
+}
+
+// The traffic keys for one direction
+struct Keys {
+    algorithm: AeadAlgorithm,
+    secret: Vec<u8>,
+    key: Vec<u8>,
+    iv: Vec<u8>,
+    sequence: u64,
+}
+
+impl Keys {
+    fn new(algorithm: AeadAlgorithm, secret: Vec<u8>) -> Keys {
+        let length = if algorithm == AeadAlgorithm::AesGcm128 { 16 } else { 32 };
+        let key = expand_label(&secret, "key", &[], length);
+        let iv = expand_label(&secret, "iv", &[], 12);
+        Keys { algorithm, secret, key, iv, sequence: 0 }
+    }
+
+    // The next generation, after a KeyUpdate
+    fn updated(&self) -> Keys {
+        Keys::new(self.algorithm, expand_label(&self.secret, "traffic upd", &[], 32))
+    }
+
+    fn nonce(&mut self) -> Vec<u8> {
+        let mut nonce = self.iv.clone();
+        for (byte, sequence) in nonce[4..].iter_mut().zip(self.sequence.to_be_bytes()) {
+            *byte ^= sequence;
+        }
+        self.sequence += 1;
+        nonce
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    ServerHello,
+    EncryptedExtensions,
+    // A CertificateRequest may come first
+    Certificate,
+    CertificateVerify,
+    Finished,
+    // The handshake is done
+    Nothing,
+}
+
+// What the handshake keeps until it is done
+struct Handshake {
+    private: [u8; 32],
+    transcript: Vec<u8>,
+    handshake_secret: Vec<u8>,
+    client_secret: Vec<u8>,
+    server_secret: Vec<u8>,
+    chain: Vec<Certificate>,
+    // The context of a CertificateRequest, answered with no certificate
+    certificate_request: Option<Vec<u8>>,
+}
+
+impl Handshake {
+    fn transcript_hash(&self) -> [u8; 32] {
+        crypto::sha256(&self.transcript)
+    }
+}
+
+enum State {
+    Connecting,
+    Handshaking(Expect),
+    Open,
+    // The server said it is done sending
+    Closed,
+}
+
+/// A TLS connection to a server
+pub struct Stream {
+    conn: u64,
+    host: String,
+    opened: u64,
+    state: State,
+    handshake: Option<Handshake>,
+    algorithm: AeadAlgorithm,
+    read: Option<Keys>,
+    write: Option<Keys>,
+    // Records as they came off the wire, and handshake messages from them
+    records: Vec<u8>,
+    messages: Vec<u8>,
+    // Records to send, and application data waiting for the handshake
+    output: Vec<u8>,
+    sent: usize,
+    pending: Vec<u8>,
+    plaintext: Vec<u8>,
+}
+
+impl Stream {
+    /// Connect to `host`, whose certificate must name it
+    pub fn connect(host: &str, port: u16) -> Result<Stream, &'static str> {
+        let address = crate::net::dns::resolve_hostname(host).ok_or("host not found")?;
+        let conn = tcp::connect(crate::net::socket::ephemeral_port(), address, port)?;
+        Ok(Stream {
+            conn,
+            host: String::from(host),
+            opened: now_ns(),
+            state: State::Connecting,
+            handshake: None,
+            algorithm: AeadAlgorithm::AesGcm128,
+            read: None,
+            write: None,
+            records: Vec::new(),
+            messages: Vec::new(),
+            output: Vec::new(),
+            sent: 0,
+            pending: Vec::new(),
+            plaintext: Vec::new(),
+        })
+    }
+
+    /// Move the handshake and data along; an error ends the connection
+    pub fn poll(&mut self) -> Result<(), &'static str> {
+        match (&self.state, tcp::state(self.conn)) {
+            (State::Connecting, Some(TcpState::SynSent | TcpState::SynReceived)) => {
+                if now_ns().saturating_sub(self.opened) > CONNECT_TIMEOUT_NS {
+                    return Err("server did not answer");
+                }
+                return Ok(());
+            }
+            (State::Connecting, Some(TcpState::Established)) => self.client_hello(),
+            (State::Connecting, _) => return Err("server refused the connection"),
+            (State::Handshaking(_), _) if now_ns().saturating_sub(self.opened) > HANDSHAKE_TIMEOUT_NS => {
+                return Err("TLS handshake timed out");
+            }
+            _ => {}
+        }
+        if !matches!(self.state, State::Closed) {
+            // All of it, so nothing is left behind once the server closes
+            while let Ok(data) = tcp::recv(self.conn, RECEIVE_CHUNK) {
+                if data.is_empty() {
+                    break;
+                }
+                self.records.extend_from_slice(&data);
+            }
+            self.read_records()?;
+        }
+        if matches!(self.state, State::Handshaking(_)) && self.peer_gone() {
+            return Err("server closed the connection during the TLS handshake");
+        }
+        self.flush();
+        Ok(())
+    }
+
+    /// Whether the handshake is done
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, State::Open)
+    }
+
+    /// Queue application data; it is encrypted and sent once the handshake
+    /// is done
+    pub fn send(&mut self, data: &[u8]) {
+        if self.is_open() {
+            for fragment in data.chunks(MAX_FRAGMENT) {
+                self.record(APPLICATION_DATA, fragment);
+            }
+            self.flush();
+        } else {
+            self.pending.extend_from_slice(data);
+        }
+    }
+
+    /// The application data that has arrived
+    pub fn receive(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.plaintext)
+    }
+
+    /// Whether the server has finished sending
+    pub fn peer_closed(&self) -> bool {
+        matches!(self.state, State::Closed) || (self.peer_gone() && self.records.is_empty())
+    }
+
+    fn peer_gone(&self) -> bool {
+        !matches!(tcp::state(self.conn), Some(TcpState::Established))
+    }
+
+    fn flush(&mut self) {
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+            return;
+        }
+        let window = tcp::send_window(self.conn).min(self.output.len() - self.sent);
+        if window > 0 && tcp::send(self.conn, &self.output[self.sent..self.sent + window]).is_ok() {
+            self.sent += window;
+        }
+    }
+
+    // One record, protected once there are keys
+    fn record(&mut self, content_type: u8, data: &[u8]) {
+        let Some(keys) = self.write.as_mut() else {
+            self.output.push(content_type);
+            self.output.extend_from_slice(&LEGACY_VERSION.to_be_bytes());
+            put_vector(&mut self.output, 2, data);
+            return;
+        };
+        let mut header = [APPLICATION_DATA, 0x03, 0x03, 0, 0];
+        header[3..].copy_from_slice(&((data.len() + 17) as u16).to_be_bytes());
+        let inner = [data, &[content_type]].concat();
+        let nonce = keys.nonce();
+        let sealed = crypto::seal(keys.algorithm, &keys.key, &nonce, &inner, &header).unwrap_or_default();
+        self.output.extend_from_slice(&header);
+        self.output.extend_from_slice(&sealed);
+    }
+
+    fn client_hello(&mut self) {
+        let (private, public) = asymmetric::x25519_keypair();
+        let mut random = [0u8; 32];
+        crypto::random_bytes(&mut random);
+        // A session ID for middleboxes that expect TLS 1.2
+        let mut session = [0u8; 32];
+        crypto::random_bytes(&mut session);
+
+        let mut body = LEGACY_VERSION.to_be_bytes().to_vec();
+        body.extend_from_slice(&random);
+        put_vector(&mut body, 1, &session);
+        let suites = [TLS_AES_128_GCM_SHA256, TLS_CHACHA20_POLY1305_SHA256];
+        put_vector(&mut body, 2, &suites.iter().flat_map(|suite| suite.to_be_bytes()).collect::<Vec<u8>>());
+        put_vector(&mut body, 1, &[0]);
+
+        let mut extensions = Vec::new();
+        // Server names are for names, not addresses
+        if !self.host.bytes().all(|c| c.is_ascii_digit() || c == b'.') {
+            let mut name = alloc::vec![0];
+            put_vector(&mut name, 2, self.host.as_bytes());
+            let mut list = Vec::new();
+            put_vector(&mut list, 2, &name);
+            put_extension(&mut extensions, SERVER_NAME, &list);
+        }
+        let mut versions = Vec::new();
+        put_vector(&mut versions, 1, &TLS_1_3.to_be_bytes());
+        put_extension(&mut extensions, SUPPORTED_VERSIONS, &versions);
+        let mut groups = Vec::new();
+        put_vector(&mut groups, 2, &X25519.to_be_bytes());
+        put_extension(&mut extensions, SUPPORTED_GROUPS, &groups);
+        let mut schemes = Vec::new();
+        let list: Vec<u8> = SIGNATURE_SCHEMES.iter().flat_map(|scheme| scheme.to_be_bytes()).collect();
+        put_vector(&mut schemes, 2, &list);
+        put_extension(&mut extensions, SIGNATURE_ALGORITHMS, &schemes);
+        let mut share = X25519.to_be_bytes().to_vec();
+        put_vector(&mut share, 2, &public);
+        let mut shares = Vec::new();
+        put_vector(&mut shares, 2, &share);
+        put_extension(&mut extensions, KEY_SHARE, &shares);
+        put_vector(&mut body, 2, &extensions);
+
+        let message = handshake_message(CLIENT_HELLO, &body);
+        self.record(HANDSHAKE, &message);
+        self.handshake = Some(Handshake {
+            private,
+            transcript: message,
+            handshake_secret: Vec::new(),
+            client_secret: Vec::new(),
+            server_secret: Vec::new(),
+            chain: Vec::new(),
+            certificate_request: None,
+        });
+        self.state = State::Handshaking(Expect::ServerHello);
+    }
+
+    // Every whole record that has arrived
+    fn read_records(&mut self) -> Result<(), &'static str> {
+        let mut used = 0;
+        while let Some(header) = self.records.get(used..used + 5) {
+            let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if length > MAX_FRAGMENT + MAX_EXPANSION {
+                return Err("TLS record too long");
+            }
+            let Some(record) = self.records.get(used..used + 5 + length) else { break };
+            let record = record.to_vec();
+            used += 5 + length;
+            self.read_record(&record)?;
+            if matches!(self.state, State::Closed) {
+                break;
+            }
+        }
+        self.records.drain(..used);
+        Ok(())
+    }
+
+    fn read_record(&mut self, record: &[u8]) -> Result<(), &'static str> {
+        let (header, payload) = record.split_at(5);
+        let (content_type, data) = match (header[0], self.read.as_mut()) {
+            // Sent for middleboxes' sake, and meaningless
+            (CHANGE_CIPHER_SPEC, _) if !matches!(self.state, State::Open) => return Ok(()),
+            (APPLICATION_DATA, Some(keys)) => {
+                let nonce = keys.nonce();
+                let mut inner = crypto::open(keys.algorithm, &keys.key, &nonce, payload, header)
+                    .map_err(|_| "TLS record failed to decrypt")?;
+                // The real content type is the last byte before the padding
+                while inner.last() == Some(&0) {
+                    inner.pop();
+                }
+                let content_type = inner.pop().ok_or("TLS record has no content type")?;
+                (content_type, inner)
+            }
+            (HANDSHAKE | ALERT, None) => (header[0], payload.to_vec()),
+            _ => return Err("unexpected TLS record"),
+        };
+        match content_type {
+            HANDSHAKE => {
+                self.messages.extend_from_slice(&data);
+                self.read_messages()
+            }
+            ALERT => match data[..] {
+                [_, CLOSE_NOTIFY] => {
+                    self.state = State::Closed;
+                    Ok(())
+                }
+                [WARNING, _] => Ok(()),
+                [FATAL, _] => Err("server sent a TLS alert"),
+                _ => Err("malformed TLS alert"),
+            },
+            APPLICATION_DATA if self.is_open() => {
+                self.plaintext.extend_from_slice(&data);
+                Ok(())
+            }
+            _ => Err("unexpected TLS record"),
+        }
+    }
+
+    fn read_messages(&mut self) -> Result<(), &'static str> {
+        while self.messages.len() >= 4 {
+            let length = Reader::new(&self.messages[1..4]).u24()?;
+            if length > MAX_HANDSHAKE_MESSAGE {
+                return Err("TLS handshake message too long");
+            }
+            if self.messages.len() < 4 + length {
+                break;
+            }
+            let message: Vec<u8> = self.messages.drain(..4 + length).collect();
+            self.message(message[0], &message)?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, kind: u8, message: &[u8]) -> Result<(), &'static str> {
+        let body = &message[4..];
+        let expect = match self.state {
+            State::Handshaking(expect) => expect,
+            _ => Expect::Nothing,
+        };
+        match (kind, expect) {
+            (SERVER_HELLO, Expect::ServerHello) => self.server_hello(body, message),
+            (ENCRYPTED_EXTENSIONS, Expect::EncryptedExtensions) => {
+                self.transcript(message);
+                self.state = State::Handshaking(Expect::Certificate);
+                Ok(())
+            }
+            (CERTIFICATE_REQUEST, Expect::Certificate) => {
+                let context = Reader::new(body).vector(1)?.to_vec();
+                let handshake = self.handshake.as_mut().ok_or(TRUNCATED)?;
+                handshake.certificate_request = Some(context);
+                self.transcript(message);
+                Ok(())
+            }
+            (CERTIFICATE, Expect::Certificate) => self.certificate(body, message),
+            (CERTIFICATE_VERIFY, Expect::CertificateVerify) => self.certificate_verify(body, message),
+            (FINISHED, Expect::Finished) => self.finished(body, message),
+            (NEW_SESSION_TICKET, Expect::Nothing) if self.is_open() => Ok(()),
+            (KEY_UPDATE, Expect::Nothing) if self.is_open() => {
+                let read = self.read.as_ref().ok_or(TRUNCATED)?;
+                self.read = Some(read.updated());
+                if body.first() == Some(&1) {
+                    // The answer goes under the old keys
+                    self.record(HANDSHAKE, &handshake_message(KEY_UPDATE, &[0]));
+                    let write = self.write.as_ref().ok_or(TRUNCATED)?;
+                    self.write = Some(write.updated());
+                }
+                Ok(())
+            }
+            _ => Err("unexpected TLS handshake message"),
+        }
+    }
+
+    fn transcript(&mut self, message: &[u8]) {
+        if let Some(handshake) = self.handshake.as_mut() {
+            handshake.transcript.extend_from_slice(message);
+        }
+    }
+
+    fn server_hello(&mut self, body: &[u8], message: &[u8]) -> Result<(), &'static str> {
+        let mut reader = Reader::new(body);
+        reader.u16()?;
+        if reader.take(32)? == RETRY_RANDOM {
+            return Err("server wants a key exchange other than X25519");
+        }
+        reader.vector(1)?;
+        self.algorithm = match reader.u16()? {
+            TLS_AES_128_GCM_SHA256 => AeadAlgorithm::AesGcm128,
+            TLS_CHACHA20_POLY1305_SHA256 => AeadAlgorithm::ChaCha20Poly1305,
+            _ => return Err("server chose a cipher suite not offered"),
+        };
+        reader.u8()?;
+        let mut extensions = Reader::new(reader.vector(2)?);
+        let (mut version, mut server_key) = (None, None);
+        while !extensions.is_empty() {
+            let kind = extensions.u16()?;
+            let mut data = Reader::new(extensions.vector(2)?);
+            match kind {
+                SUPPORTED_VERSIONS => version = Some(data.u16()?),
+                KEY_SHARE if data.u16()? == X25519 => server_key = Some(data.vector(2)?),
+                _ => {}
+            }
+        }
+        if version != Some(TLS_1_3) {
+            return Err("server does not speak TLS 1.3");
+        }
+        let server_key: [u8; 32] = server_key.and_then(|key| key.try_into().ok()).ok_or("server sent no X25519 key")?;
+
+        self.transcript(message);
+        let handshake = self.handshake.as_mut().ok_or(TRUNCATED)?;
+        let shared = asymmetric::x25519(&handshake.private, &server_key).ok_or("server sent a bad X25519 key")?;
+        let early_secret = hkdf().extract(&[0; 32], &[0; 32]);
+        let derived = expand_label(&early_secret, "derived", &crypto::sha256(&[]), 32);
+        handshake.handshake_secret = hkdf().extract(&derived, &shared);
+        let hash = handshake.transcript_hash();
+        handshake.client_secret = expand_label(&handshake.handshake_secret, "c hs traffic", &hash, 32);
+        handshake.server_secret = expand_label(&handshake.handshake_secret, "s hs traffic", &hash, 32);
+        self.read = Some(Keys::new(self.algorithm, handshake.server_secret.clone()));
+        self.write = Some(Keys::new(self.algorithm, handshake.client_secret.clone()));
+        self.state = State::Handshaking(Expect::EncryptedExtensions);
+        Ok(())
+    }
+
+    fn certificate(&mut self, body: &[u8], message: &[u8]) -> Result<(), &'static str> {
+        let mut reader = Reader::new(body);
+        reader.vector(1)?;
+        let mut list = Reader::new(reader.vector(3)?);
+        let mut chain = Vec::new();
+        while !list.is_empty() {
+            chain.push(Certificate::parse(list.vector(3)?)?);
+            list.vector(2)?;
+        }
+        let now = (crate::time::realtime_ns() / 1_000_000_000) as i64;
+        x509::verify_chain(&chain, &self.host, now, &trusted_roots())?;
+        self.transcript(message);
+        let handshake = self.handshake.as_mut().ok_or(TRUNCATED)?;
+        handshake.chain = chain;
+        self.state = State::Handshaking(Expect::CertificateVerify);
+        Ok(())
+    }
+
+    // The server's proof that it holds the certificate's private key
+    fn certificate_verify(&mut self, body: &[u8], message: &[u8]) -> Result<(), &'static str> {
+        let mut reader = Reader::new(body);
+        let algorithm = match reader.u16()? {
+            ECDSA_SECP256R1_SHA256 => Signature::Ecdsa(HashAlgorithm::SHA256),
+            RSA_PSS_RSAE_SHA256 => Signature::RsaPss(HashAlgorithm::SHA256),
+            RSA_PSS_RSAE_SHA384 => Signature::RsaPss(HashAlgorithm::SHA384),
+            RSA_PSS_RSAE_SHA512 => Signature::RsaPss(HashAlgorithm::SHA512),
+            ED25519 => Signature::Ed25519,
+            _ => return Err("server signed with a scheme not offered"),
+        };
+        let signature = reader.vector(2)?;
+        let handshake = self.handshake.as_ref().ok_or(TRUNCATED)?;
+        let mut content = alloc::vec![0x20; 64];
+        content.extend_from_slice(CERTIFICATE_VERIFY_CONTEXT);
+        content.extend_from_slice(&handshake.transcript_hash());
+        let leaf = handshake.chain.first().ok_or(TRUNCATED)?;
+        if !leaf.key.verify(algorithm, &content, signature) {
+            return Err("server's signature does not match its certificate");
+        }
+        self.transcript(message);
+        self.state = State::Handshaking(Expect::Finished);
+        Ok(())
+    }
+
+    fn finished(&mut self, body: &[u8], message: &[u8]) -> Result<(), &'static str> {
+        let mut handshake = self.handshake.take().ok_or(TRUNCATED)?;
+        let server_key = expand_label(&handshake.server_secret, "finished", &[], 32);
+        if hmac(&server_key, &handshake.transcript_hash()) != body {
+            return Err("server's Finished does not match the handshake");
+        }
+        handshake.transcript.extend_from_slice(message);
+
+        let derived = expand_label(&handshake.handshake_secret, "derived", &crypto::sha256(&[]), 32);
+        let master_secret = hkdf().extract(&derived, &[0; 32]);
+        let hash = handshake.transcript_hash();
+        let client_secret = expand_label(&master_secret, "c ap traffic", &hash, 32);
+        let server_secret = expand_label(&master_secret, "s ap traffic", &hash, 32);
+
+        // No certificate, if the server asked for one, then the client's
+        // Finished, under the handshake keys
+        if let Some(context) = handshake.certificate_request.take() {
+            let mut certificate = Vec::new();
+            put_vector(&mut certificate, 1, &context);
+            put_vector(&mut certificate, 3, &[]);
+            let certificate = handshake_message(CERTIFICATE, &certificate);
+            self.record(HANDSHAKE, &certificate);
+            handshake.transcript.extend_from_slice(&certificate);
+        }
+        let client_key = expand_label(&handshake.client_secret, "finished", &[], 32);
+        let finished = handshake_message(FINISHED, &hmac(&client_key, &handshake.transcript_hash()));
+        self.record(HANDSHAKE, &finished);
+
+        self.read = Some(Keys::new(self.algorithm, server_secret));
+        self.write = Some(Keys::new(self.algorithm, client_secret));
+        self.state = State::Open;
+        let pending = core::mem::take(&mut self.pending);
+        self.send(&pending);
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if self.is_open() {
+            self.record(ALERT, &[WARNING, CLOSE_NOTIFY]);
+            self.flush();
+        }
+        let _ = tcp::close(self.conn);
+    }
+}
+
+/// The certificates in the trusted roots directory
+pub fn trusted_roots() -> Vec<Certificate> {
+    let directory = CERTS.get();
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    let Ok(files) = vfs.list_directory_unchecked(directory) else {
+        return Vec::new();
+    };
+    files
+        .iter()
+        .filter_map(|file| vfs.read_file_unchecked(&alloc::format!("{}/{}", directory, file.name)).ok())
+        .flat_map(|data| x509::parse_file(&data))
+        .collect()
+}
@@ -0,0 +1,419 @@
+//! X.509 certificates, as far as TLS needs them
+//!
+//! Enough DER to find a certificate's names, validity, public key and
+//! extensions, and to check the signature its issuer made over it. A chain
+//! is trusted when it leads, one valid signature at a time, to one of the
+//! trusted roots.
+
+use alloc::{string::String, vec::Vec};
+use core::ops::Range;
+
+use crate::crypto::asymmetric::{EcdsaP256, RsaPublicKey};
+use crate::crypto::{self, HashAlgorithm, SignatureScheme};
+
+// Universal tags
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+// Context-specific tags
+const VERSION: u8 = 0xA0;
+const EXTENSIONS: u8 = 0xA3;
+const DNS_NAME: u8 = 0x82;
+const IP_ADDRESS: u8 = 0x87;
+
+const RSA_ENCRYPTION: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01];
+const SHA256_WITH_RSA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0B];
+const SHA384_WITH_RSA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0C];
+const SHA512_WITH_RSA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x0D];
+const EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const PRIME256V1: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const ECDSA_WITH_SHA256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x03];
+const ED25519: &[u8] = &[0x2B, 0x65, 0x70];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1D, 0x13];
+
+// Intermediates between the server's certificate and a root
+const MAX_CHAIN: usize = 8;
+
+const MALFORMED: &str = "malformed certificate";
+
+// DER elements one after another
+struct Der<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Der<'a> {
+    fn new(bytes: &'a [u8]) -> Der<'a> {
+        Der { bytes, at: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at == self.bytes.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.at).copied()
+    }
+
+    // The next element's tag, contents, and where all of it lies
+    fn element(&mut self) -> Result<(u8, &'a [u8], Range<usize>), &'static str> {
+        let start = self.at;
+        let tag = *self.bytes.get(start).ok_or(MALFORMED)?;
+        let first = *self.bytes.get(start + 1).ok_or(MALFORMED)?;
+        let (length, header) = match first {
+            0..=0x7F => (first as usize, 2),
+            0x81..=0x84 => {
+                let count = (first & 0x7F) as usize;
+                let bytes = self.bytes.get(start + 2..start + 2 + count).ok_or(MALFORMED)?;
+                (bytes.iter().fold(0usize, |length, &byte| length << 8 | byte as usize), 2 + count)
+            }
+            _ => return Err(MALFORMED),
+        };
+        let end = (start + header).checked_add(length).ok_or(MALFORMED)?;
+        let contents = self.bytes.get(start + header..end).ok_or(MALFORMED)?;
+        self.at = end;
+        Ok((tag, contents, start..end))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], &'static str> {
+        match self.element()? {
+            (found, contents, _) if found == tag => Ok(contents),
+            _ => Err(MALFORMED),
+        }
+    }
+
+    // An element only if it has this tag
+    fn optional(&mut self, tag: u8) -> Result<Option<&'a [u8]>, &'static str> {
+        if self.peek() != Some(tag) {
+            return Ok(None);
+        }
+        self.expect(tag).map(Some)
+    }
+}
+
+// A BIT STRING's bits, which must be whole bytes
+fn bits(contents: &[u8]) -> Result<&[u8], &'static str> {
+    match contents.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(MALFORMED),
+    }
+}
+
+// An INTEGER's magnitude, without the sign byte
+fn unsigned(contents: &[u8]) -> &[u8] {
+    match contents {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        _ => contents,
+    }
+}
+
+/// How a signature was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    RsaPkcs1(HashAlgorithm),
+    RsaPss(HashAlgorithm),
+    Ecdsa(HashAlgorithm),
+    Ed25519,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// An uncompressed P-256 point
+    P256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    /// A key of a kind nothing here can check signatures with
+    Unsupported,
+}
+
+impl PublicKey {
+    /// Whether `signature` over `data` was made with this key's private key
+    pub fn verify(&self, algorithm: Signature, data: &[u8], signature: &[u8]) -> bool {
+        let digest = |hash| crypto::hash(hash, data).unwrap_or_default();
+        match (self, algorithm) {
+            (PublicKey::Rsa { n, e }, Signature::RsaPkcs1(hash)) => RsaPublicKey::from_components(n, e)
+                .and_then(|key| key.verify_pkcs1(hash, data, signature))
+                .unwrap_or(false),
+            (PublicKey::Rsa { n, e }, Signature::RsaPss(hash)) => RsaPublicKey::from_components(n, e)
+                .and_then(|key| key.verify_pss(hash, data, signature))
+                .unwrap_or(false),
+            // P-256 takes the leftmost 256 bits of longer hashes
+            (PublicKey::P256(point), Signature::Ecdsa(hash)) => {
+                EcdsaP256::new().verify_prehashed(point, &digest(hash), signature).unwrap_or(false)
+            }
+            (PublicKey::Ed25519(key), Signature::Ed25519) => {
+                crypto::verify_signature(SignatureScheme::Ed25519, key, data, signature).unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct Certificate {
+    pub der: Vec<u8>,
+    tbs: Range<usize>,
+    /// The issuer's and subject's names, as DER, for matching up a chain
+    pub issuer: Vec<u8>,
+    pub subject: Vec<u8>,
+    /// Validity, in seconds since the Unix epoch
+    pub not_before: i64,
+    pub not_after: i64,
+    pub key: PublicKey,
+    signature_algorithm: Option<Signature>,
+    signature: Vec<u8>,
+    /// Host names from the subject alternative name extension
+    pub dns_names: Vec<String>,
+    pub ip_addresses: Vec<[u8; 4]>,
+    /// Whether it may issue certificates
+    pub ca: bool,
+}
+
+impl Certificate {
+    pub fn parse(der: &[u8]) -> Result<Certificate, &'static str> {
+        let (tag, contents, whole) = Der::new(der).element()?;
+        let mut certificate = Der::new(contents);
+        let (inner_tag, tbs_contents, tbs) = certificate.element()?;
+        if tag != SEQUENCE || inner_tag != SEQUENCE {
+            return Err(MALFORMED);
+        }
+        // Where the signed part lies in the whole
+        let header = whole.len() - contents.len();
+        let tbs = tbs.start + header..tbs.end + header;
+        let mut algorithm = Der::new(certificate.expect(SEQUENCE)?);
+        let signature_algorithm = signature_algorithm(algorithm.expect(OID)?);
+        let signature = bits(certificate.expect(BIT_STRING)?)?.to_vec();
+
+        let mut fields = Der::new(tbs_contents);
+        fields.optional(VERSION)?;
+        fields.expect(INTEGER)?;
+        fields.expect(SEQUENCE)?;
+        let (_, _, issuer) = fields.element()?;
+        let issuer = tbs_contents[issuer].to_vec();
+        let mut validity = Der::new(fields.expect(SEQUENCE)?);
+        let not_before = time(&mut validity)?;
+        let not_after = time(&mut validity)?;
+        let (_, _, subject) = fields.element()?;
+        let subject = tbs_contents[subject].to_vec();
+        let key = public_key(fields.expect(SEQUENCE)?)?;
+
+        let mut parsed = Certificate {
+            der: der.to_vec(),
+            tbs,
+            issuer,
+            subject,
+            not_before,
+            not_after,
+            key,
+            signature_algorithm,
+            signature,
+            dns_names: Vec::new(),
+            ip_addresses: Vec::new(),
+            ca: false,
+        };
+        // Unique identifiers, then the extensions
+        while !fields.is_empty() {
+            let (tag, contents, _) = fields.element()?;
+            if tag == EXTENSIONS {
+                parsed.extensions(contents)?;
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn extensions(&mut self, contents: &[u8]) -> Result<(), &'static str> {
+        let mut extensions = Der::new(Der::new(contents).expect(SEQUENCE)?);
+        while !extensions.is_empty() {
+            let mut extension = Der::new(extensions.expect(SEQUENCE)?);
+            let id = extension.expect(OID)?;
+            extension.optional(BOOLEAN)?;
+            let value = extension.expect(OCTET_STRING)?;
+            if id == SUBJECT_ALT_NAME {
+                let mut names = Der::new(Der::new(value).expect(SEQUENCE)?);
+                while !names.is_empty() {
+                    match names.element()? {
+                        (DNS_NAME, name, _) => self.dns_names.push(String::from_utf8_lossy(name).to_ascii_lowercase()),
+                        (IP_ADDRESS, &[a, b, c, d], _) => self.ip_addresses.push([a, b, c, d]),
+                        _ => {}
+                    }
+                }
+            } else if id == BASIC_CONSTRAINTS {
+                let mut constraints = Der::new(Der::new(value).expect(SEQUENCE)?);
+                self.ca = constraints.optional(BOOLEAN)?.is_some_and(|value| value.first() != Some(&0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `issuer`'s key made this certificate's signature
+    pub fn signed_by(&self, issuer: &Certificate) -> bool {
+        match self.signature_algorithm {
+            Some(algorithm) => issuer.key.verify(algorithm, &self.der[self.tbs.clone()], &self.signature),
+            None => false,
+        }
+    }
+
+    /// Whether the certificate names `host`, a DNS name or an IPv4 address;
+    /// a wildcard stands for one whole label
+    pub fn matches_host(&self, host: &str) -> bool {
+        if let Some(address) = parse_ipv4(host) {
+            return self.ip_addresses.contains(&address);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.dns_names.iter().any(|name| match name.strip_prefix("*.") {
+            Some(suffix) => host.split_once('.').is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+            None => *name == host,
+        })
+    }
+
+    fn valid_at(&self, now: i64) -> bool {
+        (self.not_before..=self.not_after).contains(&now)
+    }
+}
+
+fn parse_ipv4(host: &str) -> Option<[u8; 4]> {
+    let mut address = [0u8; 4];
+    let mut parts = host.split('.');
+    for byte in address.iter_mut() {
+        *byte = parts.next()?.parse().ok()?;
+    }
+    parts.next().is_none().then_some(address)
+}
+
+fn signature_algorithm(oid: &[u8]) -> Option<Signature> {
+    Some(match oid {
+        SHA256_WITH_RSA => Signature::RsaPkcs1(HashAlgorithm::SHA256),
+        SHA384_WITH_RSA => Signature::RsaPkcs1(HashAlgorithm::SHA384),
+        SHA512_WITH_RSA => Signature::RsaPkcs1(HashAlgorithm::SHA512),
+        ECDSA_WITH_SHA256 => Signature::Ecdsa(HashAlgorithm::SHA256),
+        ECDSA_WITH_SHA384 => Signature::Ecdsa(HashAlgorithm::SHA384),
+        ED25519 => Signature::Ed25519,
+        _ => return None,
+    })
+}
+
+fn public_key(info: &[u8]) -> Result<PublicKey, &'static str> {
+    let mut info = Der::new(info);
+    let mut algorithm = Der::new(info.expect(SEQUENCE)?);
+    let kind = algorithm.expect(OID)?;
+    let key = bits(info.expect(BIT_STRING)?)?;
+    Ok(match kind {
+        RSA_ENCRYPTION => {
+            let mut fields = Der::new(Der::new(key).expect(SEQUENCE)?);
+            let n = unsigned(fields.expect(INTEGER)?).to_vec();
+            let e = unsigned(fields.expect(INTEGER)?).to_vec();
+            PublicKey::Rsa { n, e }
+        }
+        EC_PUBLIC_KEY if algorithm.optional(OID)? == Some(PRIME256V1) => PublicKey::P256(key.to_vec()),
+        ED25519 => PublicKey::Ed25519(key.to_vec()),
+        _ => PublicKey::Unsupported,
+    })
+}
+
+// UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ)
+fn time(der: &mut Der) -> Result<i64, &'static str> {
+    let (tag, text, _) = der.element()?;
+    let text = match (tag, text.strip_suffix(b"Z")) {
+        (UTC_TIME | GENERALIZED_TIME, Some(text)) if text.iter().all(u8::is_ascii_digit) => text,
+        _ => return Err(MALFORMED),
+    };
+    let number = |digits: &[u8]| digits.iter().fold(0u16, |value, &digit| value * 10 + (digit - b'0') as u16);
+    let (year, rest) = match (tag, text.len()) {
+        (UTC_TIME, 12) => {
+            let year = number(&text[..2]);
+            (if year < 50 { 2000 + year } else { 1900 + year }, &text[2..])
+        }
+        (GENERALIZED_TIME, 14) => (number(&text[..4]), &text[4..]),
+        _ => return Err(MALFORMED),
+    };
+    let field = |at: usize| number(&rest[at..at + 2]) as u8;
+    let time = crate::time::DateTime {
+        year,
+        month: field(0),
+        day: field(2),
+        hour: field(4),
+        minute: field(6),
+        second: field(8),
+        millisecond: 0,
+    };
+    if !time.is_valid() {
+        return Err(MALFORMED);
+    }
+    Ok(time.to_unix())
+}
+
+/// Certificates from DER, or from PEM with any number of them
+pub fn parse_file(data: &[u8]) -> Vec<Certificate> {
+    let Ok(text) = core::str::from_utf8(data) else {
+        return Certificate::parse(data).into_iter().collect();
+    };
+    let mut certificates = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+        rest = &rest[start + 27..];
+        let Some(end) = rest.find("-----END CERTIFICATE-----") else { break };
+        if let Some(certificate) = base64(&rest[..end]).and_then(|der| Certificate::parse(&der).ok()) {
+            certificates.push(certificate);
+        }
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Check that `chain`, the server's certificate first, is for `host` and
+/// leads to one of `roots`, every certificate on the way valid at `now`
+pub fn verify_chain(chain: &[Certificate], host: &str, now: i64, roots: &[Certificate]) -> Result<(), &'static str> {
+    let leaf = chain.first().ok_or("server sent no certificate")?;
+    if !leaf.matches_host(host) {
+        return Err("server certificate is for another host");
+    }
+    let mut current = leaf;
+    for _ in 0..MAX_CHAIN {
+        if !current.valid_at(now) {
+            return Err("certificate expired or not yet valid");
+        }
+        // A trusted certificate sent as it is, or one signed by a root
+        if roots.iter().any(|root| root.der == current.der) {
+            return Ok(());
+        }
+        let trusted = |root: &&Certificate| root.subject == current.issuer && root.valid_at(now);
+        if roots.iter().filter(trusted).any(|root| current.signed_by(root)) {
+            return Ok(());
+        }
+        // Otherwise the intermediate the server sent that issued it
+        current = chain
+            .iter()
+            .filter(|issuer| !core::ptr::eq(*issuer, current) && issuer.ca && issuer.subject == current.issuer)
+            .find(|issuer| current.signed_by(issuer))
+            .ok_or("certificate not issued by a trusted root")?;
+    }
+    Err("certificate chain too long")
+}
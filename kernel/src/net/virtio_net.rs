@@ -0,0 +1,356 @@
+// Virtio network adapters
+//
+// A virtio-net device has a receive queue and a transmit queue, and every
+// packet on either starts with a 12-byte header. The receive queue is kept
+// full of pages, and a frame goes up the stack in the page the device wrote
+// it to. A packet goes out from its own pages as one chain, the header
+// pushed into the headroom in front of it, and is held until the device
+// hands the chain back.
+//
+// With the checksum and TSO features the header asks the device to finish
+// TCP checksums and to cut large segments, which QEMU leaves to the host's
+// network stack or the NIC under it.
+//
+// A device with several queue pairs gets one for each CPU, up to four,
+// turned on through the control queue. One that does RSS is told how to
+// spread flows over them; the others spread flows by a hash of their own.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER};
+use crate::virtio::{self, queue::Buffer, Queue, Transport};
+
+use super::buffer::{Offloads, PacketBuffer, Page, PAGE_SIZE};
+use super::ethernet::{EthernetController, EthernetFrame, MacAddress};
+use super::rss::{RssConfig, KEY_SIZE};
+
+const TYPE_NET: u16 = 1;
+
+// Features
+const F_CSUM: u64 = 1 << 0;
+const F_MAC: u64 = 1 << 5;
+const F_HOST_TSO4: u64 = 1 << 11;
+const F_STATUS: u64 = 1 << 16;
+const F_CTRL_VQ: u64 = 1 << 17;
+const F_MQ: u64 = 1 << 22;
+const F_RSS: u64 = 1 << 60;
+
+const QUEUE_SIZE: u16 = 256;
+const MAX_QUEUE_PAIRS: usize = 4;
+// Pages kept posted on each receive queue
+const RX_BUFFERS: usize = 128;
+
+// The header in front of every packet
+const HEADER_SIZE: usize = 12;
+const HEADER_F_NEEDS_CSUM: u8 = 1;
+const GSO_TCPV4: u8 = 1;
+
+// Device configuration
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+const CONFIG_MAX_QUEUE_PAIRS: u64 = 8;
+const CONFIG_RSS_MAX_KEY_SIZE: u64 = 17;
+const CONFIG_RSS_MAX_TABLE_LENGTH: u64 = 18;
+const CONFIG_SUPPORTED_HASH_TYPES: u64 = 20;
+const STATUS_LINK_UP: u16 = 1;
+
+// Control queue commands, and the answer to one that worked
+const CTRL_MQ: u8 = 4;
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const CTRL_MQ_RSS_CONFIG: u8 = 1;
+const CTRL_OK: u8 = 0;
+const CTRL_TIMEOUT_MS: u64 = 1000;
+
+struct RxQueue {
+    queue: Queue,
+    // Pages posted, by the head of their chain
+    posted: BTreeMap<u16, Arc<Page>>,
+}
+
+struct TxQueue {
+    queue: Queue,
+    // Packets being sent, by the head of their chain
+    sending: BTreeMap<u16, PacketBuffer>,
+}
+
+struct VirtioNet {
+    transport: Transport,
+    features: u64,
+    mac: MacAddress,
+    rx: Vec<RxQueue>,
+    tx: Vec<TxQueue>,
+    control: Option<Queue>,
+}
+
+// The interface each device was registered as
+static BOUND: Mutex<Vec<(DeviceId, String)>> = Mutex::new(Vec::new());
+
+impl RxQueue {
+    // Keep the queue full
+    fn post(&mut self) {
+        while self.posted.len() < RX_BUFFERS {
+            let Some(page) = Page::new() else {
+                break;
+            };
+            let buffer = Buffer { address: page.physical(), length: PAGE_SIZE as u32, writable: true };
+            let Ok(head) = self.queue.submit(&[buffer]) else {
+                break;
+            };
+            self.posted.insert(head, page);
+        }
+    }
+}
+
+impl TxQueue {
+    // Let go of the packets the device has sent
+    fn reclaim(&mut self) {
+        while let Some((head, _)) = self.queue.take_used() {
+            self.sending.remove(&head);
+        }
+    }
+}
+
+impl VirtioNet {
+    // Run a control command, `data` following its class and number
+    fn command(&mut self, class: u8, command: u8, data: &[u8]) -> Result<(), &'static str> {
+        let control = self.control.as_mut().ok_or("no control queue")?;
+        let mut message = alloc::vec![class, command];
+        message.extend_from_slice(data);
+        let request = PacketBuffer::from_bytes(&message).ok_or("out of memory for a command")?;
+        let ack = Page::new().ok_or("out of memory for a command")?;
+        let mut buffers: Vec<Buffer> = request
+            .segments()
+            .map(|(address, length)| Buffer { address, length: length as u32, writable: false })
+            .collect();
+        buffers.push(Buffer { address: ack.physical(), length: 1, writable: true });
+        let head = control.submit(&buffers)?;
+        control.wait(head, CTRL_TIMEOUT_MS)?;
+        if ack.bytes()[0] != CTRL_OK {
+            return Err("device refused the command");
+        }
+        Ok(())
+    }
+
+    fn config32(&self, offset: u64) -> u32 {
+        self.transport.config16(offset) as u32 | (self.transport.config16(offset + 2) as u32) << 16
+    }
+}
+
+impl Drop for VirtioNet {
+    // The device lets go of the queues and pages before they are freed
+    fn drop(&mut self) {
+        let _ = self.transport.reset();
+    }
+}
+
+impl EthernetController for VirtioNet {
+    fn get_mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        self.transmit(PacketBuffer::from_bytes(&frame.to_bytes()).ok_or("out of memory for a packet")?)
+    }
+
+    fn receive_frame(&mut self) -> Option<EthernetFrame> {
+        EthernetFrame::from_bytes(self.receive()?.head()).ok()
+    }
+
+    // Needs the control queue's receive mode commands, which are not
+    // negotiated; QEMU's devices pass every frame until told otherwise
+    fn set_promiscuous(&mut self, _enabled: bool) {}
+
+    fn get_link_status(&self) -> bool {
+        self.features & F_STATUS == 0 || self.transport.config16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn offloads(&self) -> Offloads {
+        let mut offloads = Offloads::SCATTER_GATHER;
+        if self.features & F_CSUM != 0 {
+            offloads |= Offloads::CHECKSUM;
+            if self.features & F_HOST_TSO4 != 0 {
+                offloads |= Offloads::TSO;
+            }
+        }
+        offloads
+    }
+
+    fn transmit(&mut self, packet: PacketBuffer) -> Result<(), &'static str> {
+        self.transmit_queue(0, packet)
+    }
+
+    fn receive(&mut self) -> Option<PacketBuffer> {
+        self.receive_queue(0)
+    }
+
+    fn queues(&self) -> usize {
+        self.rx.len()
+    }
+
+    fn transmit_queue(&mut self, queue: usize, mut packet: PacketBuffer) -> Result<(), &'static str> {
+        let tx = self.tx.get_mut(queue).ok_or("no such queue")?;
+        tx.reclaim();
+        let mut header = [0u8; HEADER_SIZE];
+        if let Some(checksum) = packet.checksum {
+            header[0] = HEADER_F_NEEDS_CSUM;
+            header[6..8].copy_from_slice(&(checksum.start as u16).to_le_bytes());
+            header[8..10].copy_from_slice(&(checksum.offset as u16).to_le_bytes());
+        }
+        if let Some(size) = packet.segment_size {
+            let (_, headers) = packet.tcp_headers().ok_or("not a TCP/IPv4 packet")?;
+            header[1] = GSO_TCPV4;
+            header[2..4].copy_from_slice(&(headers as u16).to_le_bytes());
+            header[4..6].copy_from_slice(&(size as u16).to_le_bytes());
+        }
+        packet.push(HEADER_SIZE).ok_or("out of memory for a packet")?.copy_from_slice(&header);
+        let buffers: Vec<Buffer> = packet
+            .segments()
+            .map(|(address, length)| Buffer { address, length: length as u32, writable: false })
+            .collect();
+        let head = tx.queue.submit(&buffers)?;
+        tx.sending.insert(head, packet);
+        Ok(())
+    }
+
+    fn receive_queue(&mut self, queue: usize) -> Option<PacketBuffer> {
+        let rx = self.rx.get_mut(queue)?;
+        let (head, length) = rx.queue.take_used()?;
+        let page = rx.posted.remove(&head)?;
+        rx.post();
+        let length = (length as usize).saturating_sub(HEADER_SIZE);
+        Some(PacketBuffer::received(page, HEADER_SIZE, length))
+    }
+
+    fn set_rss(&mut self, config: &RssConfig) -> Result<(), &'static str> {
+        if self.features & F_RSS == 0 {
+            return Err("RSS not supported");
+        }
+        if config.queues() > self.rx.len() {
+            return Err("no such queue");
+        }
+        // The table's length is a power of two the device allows; a shorter
+        // one still spreads flows evenly
+        let limit = (self.transport.config16(CONFIG_RSS_MAX_TABLE_LENGTH) as usize).min(config.table.len()).max(1);
+        let length = 1 << limit.ilog2();
+        let key_size = (self.transport.config8(CONFIG_RSS_MAX_KEY_SIZE) as usize).min(KEY_SIZE);
+        let hash_types = config.hash_types.bits() & self.config32(CONFIG_SUPPORTED_HASH_TYPES);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&hash_types.to_le_bytes());
+        data.extend_from_slice(&(length as u16 - 1).to_le_bytes());
+        // Frames the hash types leave out go to queue 0
+        data.extend_from_slice(&0u16.to_le_bytes());
+        for &queue in &config.table[..length] {
+            data.extend_from_slice(&queue.to_le_bytes());
+        }
+        data.extend_from_slice(&(self.tx.len() as u16).to_le_bytes());
+        data.push(key_size as u8);
+        data.extend_from_slice(&config.key[..key_size]);
+        self.command(CTRL_MQ, CTRL_MQ_RSS_CONFIG, &data)
+    }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+/// Binds virtio-net devices
+pub static PCI_DRIVER: VirtioNetDriver = VirtioNetDriver;
+
+pub struct VirtioNetDriver;
+
+impl Driver for VirtioNetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::PciId { vendor: virtio::VENDOR, device: 0x1000 },
+            Match::PciId { vendor: virtio::VENDOR, device: 0x1040 + TYPE_NET },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        if virtio::device_type(&pci) != Some(TYPE_NET) {
+            return Err(ProbeError::NoDevice);
+        }
+        let transport = Transport::new(&pci).map_err(ProbeError::Failed)?;
+        let wanted = F_CSUM | F_MAC | F_HOST_TSO4 | F_STATUS | F_CTRL_VQ | F_MQ | F_RSS;
+        let features = transport.negotiate(wanted).map_err(ProbeError::Failed)?;
+        if features & F_MAC == 0 {
+            let _ = transport.reset();
+            return Err(ProbeError::Failed("no MAC address"));
+        }
+
+        // Receive queue n is queue 2n, its transmit queue the one after, and
+        // the control queue comes after every pair the device has
+        let max_pairs = if features & F_MQ != 0 { transport.config16(CONFIG_MAX_QUEUE_PAIRS).max(1) } else { 1 };
+        let cpus = crate::smp::SMP_MANAGER.lock().online_cpu_count().max(1) as usize;
+        let pairs = (max_pairs as usize).min(cpus).min(MAX_QUEUE_PAIRS);
+        let mut rx = Vec::new();
+        let mut tx = Vec::new();
+        for pair in 0..pairs as u16 {
+            let queue = transport.queue(2 * pair, QUEUE_SIZE).map_err(ProbeError::Failed)?;
+            rx.push(RxQueue { queue, posted: BTreeMap::new() });
+            let queue = transport.queue(2 * pair + 1, QUEUE_SIZE).map_err(ProbeError::Failed)?;
+            tx.push(TxQueue { queue, sending: BTreeMap::new() });
+        }
+        let control = if features & F_CTRL_VQ != 0 {
+            Some(transport.queue(2 * max_pairs, QUEUE_SIZE).map_err(ProbeError::Failed)?)
+        } else {
+            None
+        };
+        let mut mac = [0u8; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = transport.config8(CONFIG_MAC + i as u64);
+        }
+        let mut net = VirtioNet { transport, features, mac: MacAddress::new(mac), rx, tx, control };
+        for rx in &mut net.rx {
+            rx.post();
+        }
+        net.transport.start();
+
+        // Without RSS the pairs are turned on here; with it, by the RSS
+        // configuration the interface sets
+        if pairs > 1 && features & F_RSS == 0 {
+            if let Err(e) = net.command(CTRL_MQ, CTRL_MQ_VQ_PAIRS_SET, &(pairs as u16).to_le_bytes()) {
+                crate::serial_println!("virtio-net: {}: one queue pair: {}", device.name, e);
+                net.rx.truncate(1);
+                net.tx.truncate(1);
+            }
+        }
+        crate::serial_println!(
+            "virtio-net: {} with {} queue pairs, offloads {:?}",
+            device.name,
+            net.rx.len(),
+            net.offloads()
+        );
+        let name = super::interface::register("eth", Box::new(net));
+        BOUND.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        BOUND.lock().retain(|(id, name)| {
+            if *id == device.id {
+                super::interface::unregister(name);
+            }
+            *id != device.id
+        });
+    }
+}
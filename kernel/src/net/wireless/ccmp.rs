@@ -0,0 +1,121 @@
+//! CCMP: AES-CCM for 802.11 data frames
+//!
+//! A protected frame's body starts with an 8-byte CCMP header holding a
+//! 48-bit packet number and the key index, and ends with an 8-byte MIC.
+//! The nonce is the frame's priority, its transmitter address and the
+//! packet number. The MIC also covers the MAC header, less the fields a
+//! retry may change, which are masked out.
+
+use alloc::vec::Vec;
+
+use crate::crypto::aead::{Aead, AesCcm};
+
+pub const HEADER_LENGTH: usize = 8;
+pub const MIC_LENGTH: usize = 8;
+
+// The extended IV bit, set in every CCMP header
+const EXT_IV: u8 = 0x20;
+
+fn ccm() -> AesCcm {
+    AesCcm::new(16, MIC_LENGTH, 13)
+}
+
+pub struct Key {
+    key: [u8; 16],
+    index: u8,
+    /// The packet number last sent with it
+    tx_pn: u64,
+    /// The highest received; anything at or below it is a replay
+    rx_pn: u64,
+}
+
+// Whether a MAC header is a QoS data frame's, and where its QoS control
+// field is
+fn qos_control(header: &[u8]) -> Option<usize> {
+    let (fc0, fc1) = (header[0], header[1]);
+    if fc0 & 0x0C != 0x08 || fc0 & 0x80 == 0 {
+        return None;
+    }
+    Some(if fc1 & 0x03 == 0x03 { 30 } else { 24 })
+}
+
+fn nonce(header: &[u8], pn: u64) -> [u8; 13] {
+    let mut nonce = [0u8; 13];
+    nonce[0] = qos_control(header).map_or(0, |at| header[at] & 0x0F);
+    nonce[1..7].copy_from_slice(&header[10..16]);
+    nonce[7..].copy_from_slice(&pn.to_be_bytes()[2..]);
+    nonce
+}
+
+// The MAC header as the MIC covers it
+fn aad(header: &[u8]) -> Vec<u8> {
+    let (fc0, fc1) = (header[0], header[1]);
+    let qos = qos_control(header);
+    let mut aad = Vec::with_capacity(30);
+    // A data frame's subtype bits other than QoS; retry, power management
+    // and more data; and a QoS frame's order bit. Protected is always set.
+    aad.push(if fc0 & 0x0C == 0x08 { fc0 & 0x8F } else { fc0 });
+    let mut flags = (fc1 & !0x38) | 0x40;
+    if qos.is_some() {
+        flags &= 0x7F;
+    }
+    aad.push(flags);
+    aad.extend_from_slice(&header[4..22]);
+    // The fragment number, without the sequence number
+    aad.extend_from_slice(&[header[22] & 0x0F, 0]);
+    if fc1 & 0x03 == 0x03 {
+        aad.extend_from_slice(&header[24..30]);
+    }
+    if let Some(at) = qos {
+        aad.extend_from_slice(&[header[at] & 0x0F, 0]);
+    }
+    aad
+}
+
+/// The key index a protected frame's body says it was sent with
+pub fn key_index(body: &[u8]) -> Option<u8> {
+    body.get(3).map(|byte| byte >> 6)
+}
+
+impl Key {
+    /// A temporal key, and the receive sequence counter the handshake gave
+    /// with it
+    pub fn new(key: &[u8], index: u8, rsc: u64) -> Result<Key, &'static str> {
+        let key = key.try_into().map_err(|_| "CCMP keys are 16 bytes")?;
+        Ok(Key { key, index, tx_pn: 0, rx_pn: rsc })
+    }
+
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// The protected body of the frame with MAC header `header`
+    pub fn encrypt(&mut self, header: &[u8], body: &[u8]) -> Result<Vec<u8>, &'static str> {
+        self.tx_pn += 1;
+        let pn = self.tx_pn.to_le_bytes();
+        let mut protected = Vec::with_capacity(HEADER_LENGTH + body.len() + MIC_LENGTH);
+        protected.extend_from_slice(&[pn[0], pn[1], 0, EXT_IV | self.index << 6, pn[2], pn[3], pn[4], pn[5]]);
+        let sealed = ccm()
+            .encrypt(&self.key, &nonce(header, self.tx_pn), body, &aad(header))
+            .map_err(|_| "CCMP encryption failed")?;
+        protected.extend_from_slice(&sealed);
+        Ok(protected)
+    }
+
+    /// The plain body of a protected frame, if its MIC holds and it is not
+    /// a replay
+    pub fn decrypt(&mut self, header: &[u8], body: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if body.len() < HEADER_LENGTH + MIC_LENGTH || body[3] & EXT_IV == 0 {
+            return Err("bad CCMP header");
+        }
+        let pn = u64::from_le_bytes([body[0], body[1], body[4], body[5], body[6], body[7], 0, 0]);
+        if pn <= self.rx_pn {
+            return Err("replayed frame");
+        }
+        let plain = ccm()
+            .decrypt(&self.key, &nonce(header, pn), &body[HEADER_LENGTH..], &aad(header))
+            .map_err(|_| "CCMP MIC failure")?;
+        self.rx_pn = pn;
+        Ok(plain)
+    }
+}
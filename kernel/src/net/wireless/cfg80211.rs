@@ -1,562 +1,214 @@
-use alloc::collections::BTreeMap;
+//! What a wireless driver does for the stack
+//!
+//! The split is Linux's cfg80211 one. A driver whose device runs the
+//! 802.11 MAC itself, scanning and joining networks when asked and passing
+//! Ethernet frames, implements `WirelessDriver` directly; RNDIS wireless
+//! adapters do. One whose device only sends and receives raw 802.11 frames
+//! is wrapped in `mac80211::SoftMac`, which does that work in software.
+//! Either way the stack chooses the network, runs the WPA2 handshake over
+//! the data path, and gives the keys it ends with back to the driver.
+//!
+//! Also here: the information elements networks describe themselves with,
+//! and the RSN element that says how one is secured.
+
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
-use super::mac80211::{Band, Channel, ChannelWidth, StationState};
+use crate::net::ethernet::{EthernetFrame, MacAddress};
 
-pub const NL80211_MAX_NR_CIPHER_SUITES: usize = 5;
-pub const NL80211_MAX_NR_AKM_SUITES: usize = 2;
+/// EAPOL, which carries the WPA handshake
+pub const ETHERTYPE_EAPOL: u16 = 0x888E;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum InterfaceType {
-    Station,
-    AccessPoint,
-    AdHoc,
-    Monitor,
-    Mesh,
-    P2PClient,
-    P2PGO,
-}
+// Element IDs
+pub const ELEMENT_SSID: u8 = 0;
+pub const ELEMENT_RATES: u8 = 1;
+pub const ELEMENT_DS_PARAMS: u8 = 3;
+pub const ELEMENT_RSN: u8 = 48;
+pub const ELEMENT_EXTENDED_RATES: u8 = 50;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum AuthType {
-    Open,
-    Shared,
-    Sae,
-    Fils,
-}
+// Suite selectors, the IEEE OUI 00-0F-AC and a type
+pub const CIPHER_TKIP: u32 = 0x000F_AC02;
+pub const CIPHER_CCMP: u32 = 0x000F_AC04;
+pub const AKM_8021X: u32 = 0x000F_AC01;
+pub const AKM_PSK: u32 = 0x000F_AC02;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum KeyManagement {
-    None,
-    Wpa2Psk,
-    Wpa2Enterprise,
-    Wpa3Psk,
-    Wpa3Enterprise,
-    Owe,
-    Fils,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum CipherSuite {
-    None,
-    Wep40,
-    Wep104,
-    Tkip,
-    Ccmp128,
-    Ccmp256,
-    Gcmp128,
-    Gcmp256,
-}
+/// Capability bit set by networks that want frames encrypted
+pub const CAPABILITY_PRIVACY: u16 = 0x0010;
 
-#[derive(Debug, Clone)]
-pub struct SecurityConfig {
-    pub auth_type: AuthType,
-    pub key_mgmt: KeyManagement,
-    pub pairwise_ciphers: Vec<CipherSuite>,
-    pub group_cipher: CipherSuite,
-    pub akm_suites: Vec<u32>,
-    pub pmf: PmfMode,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum PmfMode {
-    Disabled,
-    Optional,
-    Required,
-}
-
-impl SecurityConfig {
-    pub fn new_open() -> Self {
-        Self {
-            auth_type: AuthType::Open,
-            key_mgmt: KeyManagement::None,
-            pairwise_ciphers: vec![CipherSuite::None],
-            group_cipher: CipherSuite::None,
-            akm_suites: Vec::new(),
-            pmf: PmfMode::Disabled,
+/// How a network is secured, as far as this side is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    Open,
+    /// WPA2-Personal with CCMP
+    Wpa2Psk,
+    /// WEP, WPA with TKIP only, 802.1X and SAE, none of which are done
+    Unsupported,
+}
+
+/// What a network's RSN element offers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rsn {
+    pub group: u32,
+    pub pairwise: Vec<u32>,
+    pub akms: Vec<u32>,
+    pub capabilities: u16,
+}
+
+impl Rsn {
+    /// Parse an RSN element's body
+    pub fn parse(body: &[u8]) -> Option<Rsn> {
+        let suite = |at: usize| Some(u32::from_be_bytes(body.get(at..at + 4)?.try_into().ok()?));
+        let count = |at: usize| Some(u16::from_le_bytes(body.get(at..at + 2)?.try_into().ok()?) as usize);
+        if count(0)? != 1 {
+            return None;
         }
-    }
-
-    pub fn new_wpa2_psk() -> Self {
-        Self {
-            auth_type: AuthType::Open,
-            key_mgmt: KeyManagement::Wpa2Psk,
-            pairwise_ciphers: vec![CipherSuite::Ccmp128],
-            group_cipher: CipherSuite::Ccmp128,
-            akm_suites: vec![0x000FAC02],
-            pmf: PmfMode::Optional,
+        // Everything after the version may be left out, and defaults to
+        // CCMP and 802.1X
+        let group = suite(2).unwrap_or(CIPHER_CCMP);
+        let mut at = 6;
+        let suites = |at: &mut usize, default: u32| -> Option<Vec<u32>> {
+            let Some(n) = count(*at) else {
+                return Some(alloc::vec![default]);
+            };
+            let list = (0..n).map(|i| suite(*at + 2 + 4 * i)).collect::<Option<Vec<u32>>>()?;
+            *at += 2 + 4 * n;
+            Some(list)
+        };
+        let pairwise = suites(&mut at, CIPHER_CCMP)?;
+        let akms = suites(&mut at, AKM_8021X)?;
+        let capabilities = count(at).unwrap_or(0) as u16;
+        Some(Rsn { group, pairwise, akms, capabilities })
+    }
+}
+
+/// The RSN element this side sends: CCMP both ways, with a PSK
+pub fn rsn_element() -> Vec<u8> {
+    let mut element = alloc::vec![ELEMENT_RSN, 20, 1, 0];
+    // The group cipher, then a list of one pairwise cipher and one of one
+    // AKM, and no capabilities
+    element.extend_from_slice(&CIPHER_CCMP.to_be_bytes());
+    for suite in [CIPHER_CCMP, AKM_PSK] {
+        element.extend_from_slice(&1u16.to_le_bytes());
+        element.extend_from_slice(&suite.to_be_bytes());
+    }
+    element.extend_from_slice(&0u16.to_le_bytes());
+    element
+}
+
+/// The elements in `data`, as ID and body
+pub fn elements(data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut rest = data;
+    core::iter::from_fn(move || {
+        let (&[id, length], tail) = rest.split_first_chunk::<2>()?;
+        let body = tail.get(..length as usize)?;
+        rest = &tail[length as usize..];
+        Some((id, body))
+    })
+}
+
+/// The first element with this ID, whole, with its ID and length
+pub fn element(data: &[u8], id: u8) -> Option<&[u8]> {
+    let mut at = 0;
+    while let (Some(&element_id), Some(&length)) = (data.get(at), data.get(at + 1)) {
+        let end = at + 2 + length as usize;
+        let whole = data.get(at..end)?;
+        if element_id == id {
+            return Some(whole);
         }
+        at = end;
     }
+    None
+}
 
-    pub fn new_wpa3_psk() -> Self {
-        Self {
-            auth_type: AuthType::Sae,
-            key_mgmt: KeyManagement::Wpa3Psk,
-            pairwise_ciphers: vec![CipherSuite::Ccmp128, CipherSuite::Ccmp256],
-            group_cipher: CipherSuite::Ccmp128,
-            akm_suites: vec![0x000FAC08],
-            pmf: PmfMode::Required,
-        }
+/// The 2.4 GHz channel a frequency in MHz is, or a 5 GHz one
+pub fn frequency_channel(mhz: u32) -> u8 {
+    match mhz {
+        2484 => 14,
+        2412..=2472 => ((mhz - 2407) / 5) as u8,
+        5000..=5900 => ((mhz - 5000) / 5) as u8,
+        _ => 0,
     }
 }
 
+/// A network, as a scan found it
 #[derive(Debug, Clone)]
-pub struct BssInfo {
-    pub bssid: [u8; 6],
-    pub ssid: String,
-    pub frequency: u32,
-    pub signal: i8,
+pub struct Bss {
+    pub bssid: MacAddress,
+    pub ssid: Vec<u8>,
+    pub channel: u8,
+    /// Signal strength in dBm
+    pub signal: i32,
     pub capability: u16,
-    pub beacon_interval: u16,
-    pub security: SecurityConfig,
-    pub ies: Vec<u8>,
-    pub last_seen: u64,
-}
-
-#[derive(Debug, Clone)]
-pub struct NetworkProfile {
-    pub ssid: String,
-    pub bssid: Option<[u8; 6]>,
-    pub security: SecurityConfig,
-    pub passphrase: Option<String>,
-    pub priority: u32,
-    pub auto_connect: bool,
-    pub hidden: bool,
-}
-
-impl NetworkProfile {
-    pub fn new(ssid: String) -> Self {
-        Self {
-            ssid,
-            bssid: None,
-            security: SecurityConfig::new_open(),
-            passphrase: None,
-            priority: 0,
-            auto_connect: true,
-            hidden: false,
-        }
-    }
-}
-
-pub struct RegulatoryDomain {
-    pub alpha2: [u8; 2],
-    pub dfs_region: u8,
-    pub rules: Vec<RegulatoryRule>,
-}
-
-pub struct RegulatoryRule {
-    pub freq_range: FrequencyRange,
-    pub power_rule: PowerRule,
-    pub flags: u32,
-}
-
-pub struct FrequencyRange {
-    pub start_freq_khz: u32,
-    pub end_freq_khz: u32,
-    pub max_bandwidth_khz: u32,
-}
-
-pub struct PowerRule {
-    pub max_antenna_gain: u32,
-    pub max_eirp: u32,
-}
-
-impl RegulatoryDomain {
-    pub fn new_world() -> Self {
-        Self {
-            alpha2: [b'0', b'0'],
-            dfs_region: 0,
-            rules: vec![
-                RegulatoryRule {
-                    freq_range: FrequencyRange {
-                        start_freq_khz: 2402000,
-                        end_freq_khz: 2482000,
-                        max_bandwidth_khz: 40000,
-                    },
-                    power_rule: PowerRule {
-                        max_antenna_gain: 0,
-                        max_eirp: 2000,
-                    },
-                    flags: 0,
-                },
-                RegulatoryRule {
-                    freq_range: FrequencyRange {
-                        start_freq_khz: 5170000,
-                        end_freq_khz: 5250000,
-                        max_bandwidth_khz: 80000,
-                    },
-                    power_rule: PowerRule {
-                        max_antenna_gain: 0,
-                        max_eirp: 2000,
-                    },
-                    flags: 0,
-                },
-                RegulatoryRule {
-                    freq_range: FrequencyRange {
-                        start_freq_khz: 5250000,
-                        end_freq_khz: 5330000,
-                        max_bandwidth_khz: 80000,
-                    },
-                    power_rule: PowerRule {
-                        max_antenna_gain: 0,
-                        max_eirp: 2000,
-                    },
-                    flags: 1,
-                },
-                RegulatoryRule {
-                    freq_range: FrequencyRange {
-                        start_freq_khz: 5490000,
-                        end_freq_khz: 5730000,
-                        max_bandwidth_khz: 160000,
-                    },
-                    power_rule: PowerRule {
-                        max_antenna_gain: 0,
-                        max_eirp: 2000,
-                    },
-                    flags: 1,
-                },
-                RegulatoryRule {
-                    freq_range: FrequencyRange {
-                        start_freq_khz: 5735000,
-                        end_freq_khz: 5835000,
-                        max_bandwidth_khz: 80000,
-                    },
-                    power_rule: PowerRule {
-                        max_antenna_gain: 0,
-                        max_eirp: 3000,
-                    },
-                    flags: 0,
-                },
-            ],
-        }
-    }
-}
-
-pub struct WirelessInterface {
-    pub ifindex: u32,
-    pub name: String,
-    pub mac_addr: [u8; 6],
-    pub if_type: InterfaceType,
-    pub active: AtomicBool,
-}
-
-impl WirelessInterface {
-    pub fn new(ifindex: u32, name: String, mac_addr: [u8; 6]) -> Self {
-        Self {
-            ifindex,
-            name,
-            mac_addr,
-            if_type: InterfaceType::Station,
-            active: AtomicBool::new(false),
-        }
-    }
-
-    pub fn set_type(&mut self, if_type: InterfaceType) {
-        self.if_type = if_type;
-    }
-
-    pub fn up(&self) {
-        self.active.store(true, Ordering::Relaxed);
-    }
-
-    pub fn down(&self) {
-        self.active.store(false, Ordering::Relaxed);
-    }
-
-    pub fn is_active(&self) -> bool {
-        self.active.load(Ordering::Relaxed)
-    }
-}
-
-pub struct ScanRequest {
-    pub ssids: Vec<String>,
-    pub frequencies: Vec<u32>,
-    pub ie: Vec<u8>,
-    pub flags: u32,
-    pub rates: Vec<u32>,
-}
-
-impl ScanRequest {
-    pub fn new() -> Self {
-        Self {
-            ssids: Vec::new(),
-            frequencies: Vec::new(),
-            ie: Vec::new(),
-            flags: 0,
-            rates: Vec::new(),
-        }
-    }
-
-    pub fn add_ssid(&mut self, ssid: String) {
-        self.ssids.push(ssid);
-    }
-
-    pub fn add_frequency(&mut self, freq: u32) {
-        self.frequencies.push(freq);
-    }
-}
-
-pub struct ScanResult {
-    pub bss_list: Vec<BssInfo>,
-    pub scan_time: u64,
-    pub aborted: bool,
+    /// Its information elements, as its beacon or probe response had them
+    pub elements: Vec<u8>,
 }
 
-pub struct ConnectParams {
-    pub ssid: String,
-    pub bssid: Option<[u8; 6]>,
-    pub security: SecurityConfig,
-    pub passphrase: Option<String>,
-    pub frequency: Option<u32>,
-    pub ie: Vec<u8>,
-}
-
-pub struct DisconnectParams {
-    pub reason_code: u16,
-    pub local_state_change: bool,
-}
-
-pub struct VirtualInterface {
-    pub wiphy: u32,
-    pub ifindex: u32,
-    pub name: String,
-    pub if_type: InterfaceType,
-    pub mac_addr: [u8; 6],
-}
-
-pub struct StationInfo {
-    pub mac_addr: [u8; 6],
-    pub state: StationState,
-    pub signal: i8,
-    pub signal_avg: i8,
-    pub tx_rate: u32,
-    pub rx_rate: u32,
-    pub tx_packets: u64,
-    pub rx_packets: u64,
-    pub tx_bytes: u64,
-    pub rx_bytes: u64,
-    pub tx_failed: u32,
-    pub tx_retries: u32,
-    pub beacon_loss: u32,
-    pub connected_time: u32,
-    pub inactive_time: u32,
-}
-
-pub struct ApConfig {
-    pub ssid: String,
-    pub channel: Channel,
-    pub beacon_interval: u16,
-    pub dtim_period: u8,
-    pub hidden_ssid: bool,
-    pub security: SecurityConfig,
-    pub max_stations: u16,
-}
-
-impl ApConfig {
-    pub fn new(ssid: String) -> Self {
-        Self {
-            ssid,
-            channel: Channel {
-                frequency: 2412,
-                number: 1,
-                band: Band::Band2GHz,
-                max_power: 20,
-                flags: 0,
-            },
-            beacon_interval: 100,
-            dtim_period: 2,
-            hidden_ssid: false,
-            security: SecurityConfig::new_open(),
-            max_stations: 128,
-        }
-    }
-}
-
-pub struct MeshConfig {
-    pub mesh_id: String,
-    pub path_sel_protocol: u32,
-    pub path_metric: u32,
-    pub congestion_control: u32,
-    pub sync_method: u32,
-    pub auth_protocol: u32,
-}
-
-pub struct P2PConfig {
-    pub device_name: String,
-    pub primary_dev_type: [u8; 8],
-    pub listen_channel: Channel,
-    pub operating_channel: Channel,
-    pub go_intent: u8,
-}
-
-pub struct Cfg80211 {
-    pub wiphy_id: AtomicU32,
-    pub interfaces: BTreeMap<u32, WirelessInterface>,
-    pub regulatory: RegulatoryDomain,
-    pub scan_results: Vec<BssInfo>,
-    pub network_profiles: Vec<NetworkProfile>,
-    pub current_bss: Option<BssInfo>,
-}
-
-impl Cfg80211 {
-    pub fn new() -> Self {
-        Self {
-            wiphy_id: AtomicU32::new(0),
-            interfaces: BTreeMap::new(),
-            regulatory: RegulatoryDomain::new_world(),
-            scan_results: Vec::new(),
-            network_profiles: Vec::new(),
-            current_bss: None,
-        }
-    }
-
-    pub fn register_wiphy(&self) -> u32 {
-        self.wiphy_id.fetch_add(1, Ordering::Relaxed)
-    }
-
-    pub fn add_interface(&mut self, iface: WirelessInterface) {
-        self.interfaces.insert(iface.ifindex, iface);
-    }
-
-    pub fn remove_interface(&mut self, ifindex: u32) -> Option<WirelessInterface> {
-        self.interfaces.remove(&ifindex)
-    }
-
-    pub fn get_interface(&self, ifindex: u32) -> Option<&WirelessInterface> {
-        self.interfaces.get(&ifindex)
-    }
-
-    pub fn get_interface_mut(&mut self, ifindex: u32) -> Option<&mut WirelessInterface> {
-        self.interfaces.get_mut(&ifindex)
-    }
-
-    pub fn start_scan(&mut self, _ifindex: u32, _params: ScanRequest) -> Result<(), ()> {
-        Ok(())
+impl Bss {
+    pub fn ssid_text(&self) -> String {
+        String::from_utf8_lossy(&self.ssid).into_owned()
     }
 
-    pub fn abort_scan(&mut self, _ifindex: u32) -> Result<(), ()> {
-        Ok(())
+    pub fn rsn(&self) -> Option<Rsn> {
+        Rsn::parse(&element(&self.elements, ELEMENT_RSN)?[2..])
     }
 
-    pub fn get_scan_results(&self) -> Vec<BssInfo> {
-        self.scan_results.clone()
-    }
-
-    pub fn connect(&mut self, _ifindex: u32, params: ConnectParams) -> Result<(), ()> {
-        let profile = NetworkProfile {
-            ssid: params.ssid,
-            bssid: params.bssid,
-            security: params.security,
-            passphrase: params.passphrase,
-            priority: 0,
-            auto_connect: true,
-            hidden: false,
-        };
-        
-        self.network_profiles.push(profile);
-        Ok(())
-    }
-
-    pub fn disconnect(&mut self, _ifindex: u32, _params: DisconnectParams) -> Result<(), ()> {
-        self.current_bss = None;
-        Ok(())
-    }
-
-    pub fn add_network_profile(&mut self, profile: NetworkProfile) {
-        self.network_profiles.push(profile);
-    }
-
-    pub fn remove_network_profile(&mut self, ssid: &str) -> bool {
-        if let Some(pos) = self.network_profiles.iter().position(|p| p.ssid == ssid) {
-            self.network_profiles.remove(pos);
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn get_network_profiles(&self) -> Vec<NetworkProfile> {
-        self.network_profiles.clone()
-    }
-
-    pub fn set_regulatory_domain(&mut self, alpha2: [u8; 2]) {
-        self.regulatory.alpha2 = alpha2;
-    }
-
-    pub fn get_supported_bands(&self) -> Vec<Band> {
-        vec![Band::Band2GHz, Band::Band5GHz]
-    }
-
-    pub fn get_supported_channels(&self, band: Band) -> Vec<Channel> {
-        match band {
-            Band::Band2GHz => {
-                let mut channels = Vec::new();
-                for i in 1..=13 {
-                    channels.push(Channel {
-                        frequency: 2412 + (i - 1) * 5,
-                        number: i as u8,
-                        band: Band::Band2GHz,
-                        max_power: 20,
-                        flags: 0,
-                    });
+    pub fn security(&self) -> Security {
+        match self.rsn() {
+            Some(rsn) if rsn.pairwise.contains(&CIPHER_CCMP) && rsn.akms.contains(&AKM_PSK) => {
+                // A TKIP group key would need TKIP for broadcasts
+                if rsn.group == CIPHER_CCMP {
+                    Security::Wpa2Psk
+                } else {
+                    Security::Unsupported
                 }
-                channels
             }
-            Band::Band5GHz => {
-                vec![
-                    Channel { frequency: 5180, number: 36, band: Band::Band5GHz, max_power: 23, flags: 0 },
-                    Channel { frequency: 5200, number: 40, band: Band::Band5GHz, max_power: 23, flags: 0 },
-                    Channel { frequency: 5220, number: 44, band: Band::Band5GHz, max_power: 23, flags: 0 },
-                    Channel { frequency: 5240, number: 48, band: Band::Band5GHz, max_power: 23, flags: 0 },
-                    Channel { frequency: 5260, number: 52, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5280, number: 56, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5300, number: 60, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5320, number: 64, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5500, number: 100, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5520, number: 104, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5540, number: 108, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5560, number: 112, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5580, number: 116, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5600, number: 120, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5620, number: 124, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5640, number: 128, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5660, number: 132, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5680, number: 136, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5700, number: 140, band: Band::Band5GHz, max_power: 23, flags: 1 },
-                    Channel { frequency: 5745, number: 149, band: Band::Band5GHz, max_power: 30, flags: 0 },
-                    Channel { frequency: 5765, number: 153, band: Band::Band5GHz, max_power: 30, flags: 0 },
-                    Channel { frequency: 5785, number: 157, band: Band::Band5GHz, max_power: 30, flags: 0 },
-                    Channel { frequency: 5805, number: 161, band: Band::Band5GHz, max_power: 30, flags: 0 },
-                    Channel { frequency: 5825, number: 165, band: Band::Band5GHz, max_power: 30, flags: 0 },
-                ]
-            }
-            Band::Band6GHz => Vec::new(),
+            Some(_) => Security::Unsupported,
+            None if self.capability & CAPABILITY_PRIVACY != 0 => Security::Unsupported,
+            None => Security::Open,
         }
     }
+}
 
-    pub fn start_ap(&mut self, _ifindex: u32, _config: ApConfig) -> Result<(), ()> {
-        Ok(())
-    }
-
-    pub fn stop_ap(&mut self, _ifindex: u32) -> Result<(), ()> {
-        Ok(())
-    }
-
-    pub fn join_mesh(&mut self, _ifindex: u32, _config: MeshConfig) -> Result<(), ()> {
-        Ok(())
-    }
-
-    pub fn leave_mesh(&mut self, _ifindex: u32) -> Result<(), ()> {
-        Ok(())
-    }
-
-    pub fn start_p2p(&mut self, _ifindex: u32, _config: P2PConfig) -> Result<(), ()> {
-        Ok(())
-    }
-
-    pub fn stop_p2p(&mut self, _ifindex: u32) -> Result<(), ()> {
-        Ok(())
-    }
-}
\ No newline at end of file
+/// A key the handshake ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    /// The temporal key for frames to and from the access point
+    Pairwise,
+    /// A group key for broadcasts, by key index, with the receive sequence
+    /// counter it starts from
+    Group { index: u8, rsc: u64 },
+}
+
+/// What a driver tells the stack, from its `poll`
+pub enum Event {
+    ScanDone(Vec<Bss>),
+    /// Associated with `bssid`. `rsn` is the RSN element the association
+    /// request carried, which the handshake repeats to the access point.
+    Connected {
+        bssid: MacAddress,
+        rsn: Option<Vec<u8>>,
+    },
+    /// Could not join the network asked for
+    ConnectFailed(&'static str),
+    /// Left the network, with the reason code the access point gave, or 0
+    Disconnected {
+        reason: u16,
+    },
+    Frame(EthernetFrame),
+}
+
+pub trait WirelessDriver: Send {
+    fn mac_address(&self) -> MacAddress;
+    /// Start looking for networks; `Event::ScanDone` gives what was found
+    fn scan(&mut self) -> Result<(), &'static str>;
+    /// Join `bss`, sending `rsn` in the association request when it is
+    /// secured
+    fn connect(&mut self, bss: &Bss, rsn: Option<&[u8]>) -> Result<(), &'static str>;
+    fn disconnect(&mut self);
+    /// Encrypt and decrypt with `key` from now on
+    fn add_key(&mut self, kind: KeyKind, key: &[u8]) -> Result<(), &'static str>;
+    /// Send a frame to the network, as Ethernet has it
+    fn send(&mut self, frame: &EthernetFrame) -> Result<(), &'static str>;
+    /// What has happened since the last call, one event at a time
+    fn poll(&mut self) -> Option<Event>;
+}
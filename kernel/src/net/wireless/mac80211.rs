@@ -1,708 +1,468 @@
+//! SoftMAC: the 802.11 MAC in software, for radios that only pass frames
+//!
+//! `SoftMac` does over a `Radio` what a full-MAC device does itself. It
+//! scans by tuning to each channel in turn, sending a probe request and
+//! listening for beacons and probe responses. It joins a network with open
+//! system authentication and an association request. On the data path an
+//! Ethernet frame becomes a data frame to the access point, its type
+//! behind an LLC/SNAP header, and frames from the access point go back the
+//! same way; once the handshake has given keys, both are protected with
+//! CCMP. Retries, rates and the FCS are left to the radio.
+//!
+//! Only 2.4 GHz channels are scanned unless the radio says otherwise, and
+//! A-MSDUs and fragments are dropped.
+
 use alloc::collections::VecDeque;
-use alloc::string::String;
 use alloc::vec::Vec;
-use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
-
-pub const IEEE80211_MAX_SSID_LEN: usize = 32;
-pub const IEEE80211_MAX_FRAME_LEN: usize = 2352;
-pub const IEEE80211_MAX_QUEUES: usize = 16;
-pub const IEEE80211_QOS_CTL_LEN: usize = 2;
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum Band {
-    Band2GHz,
-    Band5GHz,
-    Band6GHz,
+
+use super::ccmp;
+use super::cfg80211::{
+    self, Bss, Event, KeyKind, WirelessDriver, ELEMENT_DS_PARAMS, ELEMENT_EXTENDED_RATES, ELEMENT_RATES, ELEMENT_SSID,
+};
+use crate::net::ethernet::{EthernetFrame, MacAddress};
+
+/// A frame the radio received, with a good FCS, which is taken off
+pub struct RxFrame {
+    pub data: Vec<u8>,
+    /// Signal strength in dBm
+    pub signal: i32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ChannelWidth {
-    Width20,
-    Width40,
-    Width80,
-    Width160,
+pub trait Radio: Send {
+    fn mac_address(&self) -> MacAddress;
+    /// The channels it may tune to
+    fn channels(&self) -> &[u8] {
+        &CHANNELS_2GHZ
+    }
+    fn set_channel(&mut self, channel: u8) -> Result<(), &'static str>;
+    /// Send a frame; the radio adds the FCS
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), &'static str>;
+    fn receive(&mut self) -> Option<RxFrame>;
 }
 
-#[derive(Debug, Clone)]
-pub struct Channel {
-    pub frequency: u32,
-    pub number: u8,
-    pub band: Band,
-    pub max_power: i8,
-    pub flags: u32,
+const CHANNELS_2GHZ: [u8; 13] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+// Time on each channel while scanning
+const DWELL_MS: u64 = 120;
+// Time to wait for the access point to answer, and how many times to ask
+const ANSWER_MS: u64 = 300;
+const TRIES: u32 = 3;
+// Frames taken from the radio per poll
+const RX_BUDGET: usize = 32;
+
+// Frame control, first byte: type and subtype
+const TYPE_MASK: u8 = 0x0C;
+const TYPE_MANAGEMENT: u8 = 0x00;
+const TYPE_DATA: u8 = 0x08;
+const ASSOCIATION_REQUEST: u8 = 0x00;
+const ASSOCIATION_RESPONSE: u8 = 0x10;
+const PROBE_REQUEST: u8 = 0x40;
+const PROBE_RESPONSE: u8 = 0x50;
+const BEACON: u8 = 0x80;
+const DISASSOCIATION: u8 = 0xA0;
+const AUTHENTICATION: u8 = 0xB0;
+const DEAUTHENTICATION: u8 = 0xC0;
+// Data subtype bits: QoS, and no data
+const SUBTYPE_QOS: u8 = 0x80;
+const SUBTYPE_NULL: u8 = 0x40;
+
+// Frame control, second byte
+const TO_DS: u8 = 0x01;
+const FROM_DS: u8 = 0x02;
+const MORE_FRAGMENTS: u8 = 0x04;
+const PROTECTED: u8 = 0x40;
+
+const HEADER_LENGTH: usize = 24;
+// The QoS control bit that says the body is an A-MSDU
+const QOS_AMSDU: u8 = 0x80;
+
+const LLC_SNAP: [u8; 6] = [0xAA, 0xAA, 0x03, 0x00, 0x00, 0x00];
+// The bridge-tunnel encapsulation some access points use for IPX and AARP
+const LLC_BRIDGE_TUNNEL: [u8; 6] = [0xAA, 0xAA, 0x03, 0x00, 0x00, 0xF8];
+
+// The rates asked for, in 500 kb/s units, the 802.11b ones basic
+const RATES: [u8; 8] = [0x82, 0x84, 0x8B, 0x96, 0x0C, 0x12, 0x18, 0x24];
+const EXTENDED_RATES: [u8; 4] = [0x30, 0x48, 0x60, 0x6C];
+
+const CAPABILITY_ESS: u16 = 0x0001;
+const LISTEN_INTERVAL: u16 = 10;
+// Deauthentication reason: leaving
+const REASON_LEAVING: u16 = 3;
+
+enum State {
+    Idle,
+    Scanning { channel: usize, until: u64, found: Vec<Bss> },
+    Authenticating { tries: u32, until: u64 },
+    Associating { tries: u32, until: u64 },
+    Associated,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum FrameType {
-    Management,
-    Control,
-    Data,
-    Extension,
+pub struct SoftMac<R: Radio> {
+    radio: R,
+    mac: MacAddress,
+    state: State,
+    /// The network being joined, or joined
+    bss: Option<Bss>,
+    rsn: Option<Vec<u8>>,
+    sequence: u16,
+    pairwise: Option<ccmp::Key>,
+    group: [Option<ccmp::Key>; 4],
+    events: VecDeque<Event>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ManagementSubtype {
-    AssocRequest,
-    AssocResponse,
-    ReassocRequest,
-    ReassocResponse,
-    ProbeRequest,
-    ProbeResponse,
-    Beacon,
-    Atim,
-    Disassoc,
-    Auth,
-    Deauth,
-    Action,
+fn now_ms() -> u64 {
+    crate::time::clocksource::now_ns() / 1_000_000
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum ControlSubtype {
-    BlockAckReq,
-    BlockAck,
-    PsPoll,
-    Rts,
-    Cts,
-    Ack,
-    CfEnd,
-    CfEndAck,
+fn le16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum DataSubtype {
-    Data,
-    DataCfAck,
-    DataCfPoll,
-    DataCfAckPoll,
-    Null,
-    CfAck,
-    CfPoll,
-    CfAckPoll,
-    QosData,
-    QosDataCfAck,
-    QosDataCfPoll,
-    QosDataCfAckPoll,
-    QosNull,
-    QosCfPoll,
-    QosCfAckPoll,
+fn address(data: &[u8], at: usize) -> MacAddress {
+    MacAddress::from_bytes(&data[at..at + 6]).unwrap()
 }
 
-#[repr(C, packed)]
-pub struct FrameControl {
-    pub version: u8,
-    pub frame_type: u8,
-    pub subtype: u8,
-    pub to_ds: bool,
-    pub from_ds: bool,
-    pub more_frag: bool,
-    pub retry: bool,
-    pub pwr_mgmt: bool,
-    pub more_data: bool,
-    pub protected: bool,
-    pub order: bool,
+fn push_element(body: &mut Vec<u8>, id: u8, data: &[u8]) {
+    body.push(id);
+    body.push(data.len() as u8);
+    body.extend_from_slice(data);
 }
 
-impl FrameControl {
-    pub fn new(frame_type: FrameType, subtype: u8) -> Self {
-        Self {
-            version: 0,
-            frame_type: match frame_type {
-                FrameType::Management => 0,
-                FrameType::Control => 1,
-                FrameType::Data => 2,
-                FrameType::Extension => 3,
-            },
-            subtype,
-            to_ds: false,
-            from_ds: false,
-            more_frag: false,
-            retry: false,
-            pwr_mgmt: false,
-            more_data: false,
-            protected: false,
-            order: false,
+impl<R: Radio> SoftMac<R> {
+    pub fn new(radio: R) -> Self {
+        let mac = radio.mac_address();
+        SoftMac {
+            radio,
+            mac,
+            state: State::Idle,
+            bss: None,
+            rsn: None,
+            sequence: 0,
+            pairwise: None,
+            group: [None, None, None, None],
+            events: VecDeque::new(),
         }
     }
 
-    pub fn to_bytes(&self) -> [u8; 2] {
-        let mut fc = 0u16;
-        fc |= (self.version as u16) & 0x3;
-        fc |= ((self.frame_type as u16) & 0x3) << 2;
-        fc |= ((self.subtype as u16) & 0xF) << 4;
-        fc |= (self.to_ds as u16) << 8;
-        fc |= (self.from_ds as u16) << 9;
-        fc |= (self.more_frag as u16) << 10;
-        fc |= (self.retry as u16) << 11;
-        fc |= (self.pwr_mgmt as u16) << 12;
-        fc |= (self.more_data as u16) << 13;
-        fc |= (self.protected as u16) << 14;
-        fc |= (self.order as u16) << 15;
-        fc.to_le_bytes()
+    // A MAC header, with the next sequence number
+    fn header(&mut self, fc0: u8, fc1: u8, addr1: MacAddress, addr3: MacAddress) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.extend_from_slice(&[fc0, fc1, 0, 0]);
+        header.extend_from_slice(addr1.as_bytes());
+        header.extend_from_slice(self.mac.as_bytes());
+        header.extend_from_slice(addr3.as_bytes());
+        header.extend_from_slice(&(self.sequence << 4).to_le_bytes());
+        self.sequence = (self.sequence + 1) & 0x0FFF;
+        header
     }
 
-    pub fn from_bytes(bytes: [u8; 2]) -> Self {
-        let fc = u16::from_le_bytes(bytes);
-        Self {
-            version: (fc & 0x3) as u8,
-            frame_type: ((fc >> 2) & 0x3) as u8,
-            subtype: ((fc >> 4) & 0xF) as u8,
-            to_ds: (fc >> 8) & 1 == 1,
-            from_ds: (fc >> 9) & 1 == 1,
-            more_frag: (fc >> 10) & 1 == 1,
-            retry: (fc >> 11) & 1 == 1,
-            pwr_mgmt: (fc >> 12) & 1 == 1,
-            more_data: (fc >> 13) & 1 == 1,
-            protected: (fc >> 14) & 1 == 1,
-            order: (fc >> 15) & 1 == 1,
+    fn send_management(&mut self, subtype: u8, to: MacAddress, bssid: MacAddress, body: &[u8]) {
+        let mut frame = self.header(TYPE_MANAGEMENT | subtype, 0, to, bssid);
+        frame.extend_from_slice(body);
+        if let Err(e) = self.radio.transmit(&frame) {
+            crate::serial_println!("wifi: cannot send a management frame: {}", e);
         }
     }
-}
-
-#[repr(C, packed)]
-pub struct MacHeader {
-    pub frame_control: [u8; 2],
-    pub duration: u16,
-    pub addr1: [u8; 6],
-    pub addr2: [u8; 6],
-    pub addr3: [u8; 6],
-    pub seq_ctrl: u16,
-}
 
-impl MacHeader {
-    pub fn new(fc: FrameControl, dst: [u8; 6], src: [u8; 6], bssid: [u8; 6]) -> Self {
-        Self {
-            frame_control: fc.to_bytes(),
-            duration: 0,
-            addr1: dst,
-            addr2: src,
-            addr3: bssid,
-            seq_ctrl: 0,
-        }
+    fn push_rates(body: &mut Vec<u8>) {
+        push_element(body, ELEMENT_RATES, &RATES);
+        push_element(body, ELEMENT_EXTENDED_RATES, &EXTENDED_RATES);
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct BeaconFrame {
-    pub timestamp: u64,
-    pub beacon_interval: u16,
-    pub capability_info: u16,
-    pub ssid: String,
-    pub supported_rates: Vec<u8>,
-    pub channel: u8,
-    pub rsn_ie: Option<Vec<u8>>,
-}
-
-#[derive(Debug, Clone)]
-pub struct ProbeRequest {
-    pub ssid: Option<String>,
-    pub supported_rates: Vec<u8>,
-    pub extended_rates: Option<Vec<u8>>,
-}
-
-#[derive(Debug, Clone)]
-pub struct AuthFrame {
-    pub algorithm: u16,
-    pub seq_num: u16,
-    pub status_code: u16,
-    pub challenge_text: Option<Vec<u8>>,
-}
-
-#[derive(Debug, Clone)]
-pub struct AssocRequest {
-    pub capability_info: u16,
-    pub listen_interval: u16,
-    pub ssid: String,
-    pub supported_rates: Vec<u8>,
-    pub rsn_ie: Option<Vec<u8>>,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum StationState {
-    Idle,
-    Scanning,
-    Authenticating,
-    Associating,
-    Associated,
-    Disconnected,
-}
 
-pub struct Station {
-    pub mac_addr: [u8; 6],
-    pub state: StationState,
-    pub bssid: Option<[u8; 6]>,
-    pub ssid: Option<String>,
-    pub channel: Option<Channel>,
-    pub signal_strength: i8,
-    pub tx_rate: u32,
-    pub rx_rate: u32,
-    pub tx_packets: AtomicU64,
-    pub rx_packets: AtomicU64,
-    pub tx_bytes: AtomicU64,
-    pub rx_bytes: AtomicU64,
-}
-
-impl Station {
-    pub fn new(mac_addr: [u8; 6]) -> Self {
-        Self {
-            mac_addr,
-            state: StationState::Idle,
-            bssid: None,
-            ssid: None,
-            channel: None,
-            signal_strength: -100,
-            tx_rate: 0,
-            rx_rate: 0,
-            tx_packets: AtomicU64::new(0),
-            rx_packets: AtomicU64::new(0),
-            tx_bytes: AtomicU64::new(0),
-            rx_bytes: AtomicU64::new(0),
-        }
+    // A probe request for any network
+    fn probe(&mut self) {
+        let mut body = Vec::new();
+        push_element(&mut body, ELEMENT_SSID, &[]);
+        Self::push_rates(&mut body);
+        self.send_management(PROBE_REQUEST, MacAddress::BROADCAST, MacAddress::BROADCAST, &body);
     }
-}
 
-pub struct TxQueue {
-    pub queue_id: usize,
-    pub frames: VecDeque<Vec<u8>>,
-    pub max_len: usize,
-    pub dropped: AtomicU32,
-}
-
-impl TxQueue {
-    pub fn new(queue_id: usize, max_len: usize) -> Self {
-        Self {
-            queue_id,
-            frames: VecDeque::new(),
-            max_len,
-            dropped: AtomicU32::new(0),
-        }
+    fn authenticate(&mut self, tries: u32) {
+        let Some(bssid) = self.bss.as_ref().map(|bss| bss.bssid) else {
+            return;
+        };
+        // Open system, first of the exchange, and no status
+        self.send_management(AUTHENTICATION, bssid, bssid, &[0, 0, 1, 0, 0, 0]);
+        self.state = State::Authenticating { tries, until: now_ms() + ANSWER_MS };
     }
 
-    pub fn enqueue(&mut self, frame: Vec<u8>) -> Result<(), ()> {
-        if self.frames.len() >= self.max_len {
-            self.dropped.fetch_add(1, Ordering::Relaxed);
-            return Err(());
+    fn associate(&mut self, tries: u32) {
+        let Some(bss) = self.bss.as_ref() else {
+            return;
+        };
+        let bssid = bss.bssid;
+        let mut body = Vec::new();
+        body.extend_from_slice(&CAPABILITY_ESS.to_le_bytes());
+        body.extend_from_slice(&LISTEN_INTERVAL.to_le_bytes());
+        push_element(&mut body, ELEMENT_SSID, &bss.ssid);
+        Self::push_rates(&mut body);
+        if let Some(rsn) = &self.rsn {
+            body.extend_from_slice(rsn);
         }
-        self.frames.push_back(frame);
-        Ok(())
+        self.send_management(ASSOCIATION_REQUEST, bssid, bssid, &body);
+        self.state = State::Associating { tries, until: now_ms() + ANSWER_MS };
     }
 
-    pub fn dequeue(&mut self) -> Option<Vec<u8>> {
-        self.frames.pop_front()
+    // Forget the network and its keys
+    fn reset(&mut self) {
+        self.state = State::Idle;
+        self.bss = None;
+        self.rsn = None;
+        self.pairwise = None;
+        self.group = [None, None, None, None];
     }
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct RateInfo {
-    pub bitrate: u32,
-    pub mcs_index: Option<u8>,
-    pub nss: u8,
-    pub bandwidth: ChannelWidth,
-    pub guard_interval: u16,
-}
 
-pub struct RateControl {
-    pub current_rate: RateInfo,
-    pub rates: Vec<RateInfo>,
-    pub tx_success: AtomicU32,
-    pub tx_failed: AtomicU32,
-    pub last_update: u64,
-}
-
-impl RateControl {
-    pub fn new() -> Self {
-        let rates = vec![
-            RateInfo { bitrate: 1000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 2000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 5500, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 11000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 6000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 9000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 12000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 18000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 24000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 36000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 48000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-            RateInfo { bitrate: 54000, mcs_index: None, nss: 1, bandwidth: ChannelWidth::Width20, guard_interval: 800 },
-        ];
-
-        Self {
-            current_rate: rates[0],
-            rates,
-            tx_success: AtomicU32::new(0),
-            tx_failed: AtomicU32::new(0),
-            last_update: 0,
-        }
-    }
-
-    pub fn update_rate(&mut self, success: bool) {
-        if success {
-            self.tx_success.fetch_add(1, Ordering::Relaxed);
-        } else {
-            self.tx_failed.fetch_add(1, Ordering::Relaxed);
-        }
-
-        let success_count = self.tx_success.load(Ordering::Relaxed);
-        let failed_count = self.tx_failed.load(Ordering::Relaxed);
-        let total = success_count + failed_count;
-
-        if total >= 100 {
-            let success_rate = (success_count * 100) / total;
-            
-            if success_rate > 90 {
-                self.increase_rate();
-            } else if success_rate < 60 {
-                self.decrease_rate();
-            }
-
-            self.tx_success.store(0, Ordering::Relaxed);
-            self.tx_failed.store(0, Ordering::Relaxed);
-        }
+    fn fail(&mut self, reason: &'static str) {
+        self.reset();
+        self.events.push_back(Event::ConnectFailed(reason));
     }
 
-    fn increase_rate(&mut self) {
-        for (i, rate) in self.rates.iter().enumerate() {
-            if rate.bitrate == self.current_rate.bitrate && i < self.rates.len() - 1 {
-                self.current_rate = self.rates[i + 1];
-                break;
+    fn tune(&mut self, index: usize) -> bool {
+        let Some(&channel) = self.radio.channels().get(index) else {
+            return false;
+        };
+        match self.radio.set_channel(channel) {
+            Ok(()) => {
+                self.probe();
+                true
             }
-        }
-    }
-
-    fn decrease_rate(&mut self) {
-        for (i, rate) in self.rates.iter().enumerate() {
-            if rate.bitrate == self.current_rate.bitrate && i > 0 {
-                self.current_rate = self.rates[i - 1];
-                break;
+            Err(e) => {
+                crate::serial_println!("wifi: cannot tune to channel {}: {}", channel, e);
+                false
             }
         }
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum PowerSaveMode {
-    Active,
-    LightSleep,
-    DeepSleep,
-}
-
-pub struct PowerManagement {
-    pub mode: PowerSaveMode,
-    pub dtim_period: u8,
-    pub listen_interval: u16,
-    pub last_beacon: u64,
-    pub buffered_frames: VecDeque<Vec<u8>>,
-}
-
-impl PowerManagement {
-    pub fn new() -> Self {
-        Self {
-            mode: PowerSaveMode::Active,
-            dtim_period: 1,
-            listen_interval: 1,
-            last_beacon: 0,
-            buffered_frames: VecDeque::new(),
+    // A beacon or probe response, while scanning
+    fn found(&mut self, bssid: MacAddress, body: &[u8], signal: i32) {
+        let State::Scanning { channel, found, .. } = &mut self.state else {
+            return;
+        };
+        let (Some(capability), Some(elements)) = (le16(body, 10), body.get(12..)) else {
+            return;
+        };
+        let ssid = cfg80211::element(elements, ELEMENT_SSID).map_or(&[][..], |element| &element[2..]);
+        // Beacons heard on a neighbouring channel say which is theirs
+        let channel = cfg80211::element(elements, ELEMENT_DS_PARAMS)
+            .and_then(|element| element.get(2).copied())
+            .unwrap_or_else(|| self.radio.channels().get(*channel).copied().unwrap_or(0));
+        let bss = Bss { bssid, ssid: ssid.to_vec(), channel, signal, capability, elements: elements.to_vec() };
+        match found.iter_mut().find(|known| known.bssid == bssid) {
+            Some(known) => *known = bss,
+            None => found.push(bss),
         }
     }
 
-    pub fn enter_power_save(&mut self, mode: PowerSaveMode) {
-        self.mode = mode;
-    }
-
-    pub fn exit_power_save(&mut self) {
-        self.mode = PowerSaveMode::Active;
-    }
-
-    pub fn should_wake(&self, current_time: u64) -> bool {
-        match self.mode {
-            PowerSaveMode::Active => true,
-            PowerSaveMode::LightSleep => {
-                (current_time - self.last_beacon) >= (self.listen_interval as u64 * 100)
+    fn management(&mut self, subtype: u8, from: MacAddress, bssid: MacAddress, body: &[u8], signal: i32) {
+        let ours = self.bss.as_ref().is_some_and(|bss| bss.bssid == bssid && from == bssid);
+        match subtype {
+            BEACON | PROBE_RESPONSE => self.found(bssid, body, signal),
+            AUTHENTICATION if ours && matches!(self.state, State::Authenticating { .. }) => {
+                // Open system, the answer, and its status
+                match (le16(body, 0), le16(body, 2), le16(body, 4)) {
+                    (Some(0), Some(2), Some(0)) => self.associate(1),
+                    (Some(0), Some(2), Some(_)) => self.fail("authentication refused"),
+                    _ => {}
+                }
             }
-            PowerSaveMode::DeepSleep => {
-                (current_time - self.last_beacon) >= (self.dtim_period as u64 * self.listen_interval as u64 * 100)
+            ASSOCIATION_RESPONSE if ours && matches!(self.state, State::Associating { .. }) => match le16(body, 2) {
+                Some(0) => {
+                    self.state = State::Associated;
+                    self.events.push_back(Event::Connected { bssid, rsn: self.rsn.clone() });
+                }
+                Some(_) => self.fail("association refused"),
+                None => {}
+            },
+            DEAUTHENTICATION | DISASSOCIATION if ours => {
+                let reason = le16(body, 0).unwrap_or(0);
+                if matches!(self.state, State::Associated) {
+                    self.reset();
+                    self.events.push_back(Event::Disconnected { reason });
+                } else if !matches!(self.state, State::Idle) {
+                    self.fail("refused by the access point");
+                }
             }
+            _ => {}
         }
     }
-}
-
-pub struct CsmaCA {
-    pub cw_min: u32,
-    pub cw_max: u32,
-    pub current_cw: u32,
-    pub backoff_counter: u32,
-    pub retry_count: u32,
-    pub max_retries: u32,
-}
 
-impl CsmaCA {
-    pub fn new() -> Self {
-        Self {
-            cw_min: 15,
-            cw_max: 1023,
-            current_cw: 15,
-            backoff_counter: 0,
-            retry_count: 0,
-            max_retries: 7,
+    // A data frame from the access point, back into an Ethernet frame
+    fn data(&mut self, frame: &[u8]) {
+        let (fc0, fc1) = (frame[0], frame[1]);
+        let Some(bssid) = self.bss.as_ref().map(|bss| bss.bssid) else {
+            return;
+        };
+        if !matches!(self.state, State::Associated)
+            || fc1 & (TO_DS | FROM_DS) != FROM_DS
+            || address(frame, 10) != bssid
+            || fc0 & SUBTYPE_NULL != 0
+            || fc1 & MORE_FRAGMENTS != 0
+            || frame[22] & 0x0F != 0
+        {
+            return;
         }
-    }
-
-    pub fn reset(&mut self) {
-        self.current_cw = self.cw_min;
-        self.backoff_counter = 0;
-        self.retry_count = 0;
-    }
-
-    pub fn collision(&mut self) {
-        self.retry_count += 1;
-        if self.retry_count <= self.max_retries {
-            self.current_cw = core::cmp::min(self.current_cw * 2 + 1, self.cw_max);
-            self.backoff_counter = self.random_backoff();
+        let length = if fc0 & SUBTYPE_QOS != 0 { HEADER_LENGTH + 2 } else { HEADER_LENGTH };
+        let Some((header, body)) = frame.split_at_checked(length) else {
+            return;
+        };
+        if fc0 & SUBTYPE_QOS != 0 && header[HEADER_LENGTH] & QOS_AMSDU != 0 {
+            return;
         }
-    }
-
-    pub fn success(&mut self) {
-        self.reset();
-    }
-
-    pub fn can_retry(&self) -> bool {
-        self.retry_count < self.max_retries
-    }
-
-    fn random_backoff(&self) -> u32 {
-        (self.current_cw + 1) / 2
-    }
-
-    pub fn decrement_backoff(&mut self) -> bool {
-        if self.backoff_counter > 0 {
-            self.backoff_counter -= 1;
-            false
+        let (destination, source) = (address(frame, 4), address(frame, 16));
+
+        let body = if fc1 & PROTECTED != 0 {
+            let Some(index) = ccmp::key_index(body) else {
+                return;
+            };
+            let key = match destination.is_multicast() {
+                true => self.group[index as usize].as_mut(),
+                false => self.pairwise.as_mut(),
+            };
+            match key.map(|key| key.decrypt(header, body)) {
+                Some(Ok(plain)) => plain,
+                _ => return,
+            }
+        } else if self.pairwise.is_some() {
+            // Nothing comes in the clear once there are keys
+            return;
         } else {
-            true
+            body.to_vec()
+        };
+
+        if body.len() < 8 || (body[..6] != LLC_SNAP && body[..6] != LLC_BRIDGE_TUNNEL) {
+            return;
         }
+        let ethertype = u16::from_be_bytes([body[6], body[7]]);
+        self.events.push_back(Event::Frame(EthernetFrame::new(destination, source, ethertype, body[8..].to_vec())));
     }
-}
-
-pub struct Aggregation {
-    pub ampdu_enabled: bool,
-    pub amsdu_enabled: bool,
-    pub max_ampdu_length: u32,
-    pub max_amsdu_length: u32,
-    pub pending_frames: VecDeque<Vec<u8>>,
-}
 
-impl Aggregation {
-    pub fn new() -> Self {
-        Self {
-            ampdu_enabled: true,
-            amsdu_enabled: true,
-            max_ampdu_length: 65535,
-            max_amsdu_length: 7935,
-            pending_frames: VecDeque::new(),
+    fn receive(&mut self, frame: RxFrame) {
+        let data = &frame.data;
+        if data.len() < HEADER_LENGTH {
+            return;
+        }
+        let destination = address(data, 4);
+        if destination != self.mac && !destination.is_multicast() {
+            return;
+        }
+        match data[0] & TYPE_MASK {
+            TYPE_MANAGEMENT => {
+                let (from, bssid) = (address(data, 10), address(data, 16));
+                self.management(data[0] & 0xF0, from, bssid, &data[HEADER_LENGTH..], frame.signal)
+            }
+            TYPE_DATA => self.data(data),
+            _ => {}
         }
     }
 
-    pub fn aggregate_amsdu(&mut self, frames: Vec<Vec<u8>>) -> Vec<u8> {
-        let mut aggregated = Vec::new();
-        let mut current_len = 0;
-
-        for frame in frames {
-            if current_len + frame.len() + 14 > self.max_amsdu_length as usize {
-                break;
+    // Move on when a channel has been listened to long enough, or the
+    // access point has not answered
+    fn timers(&mut self) {
+        let now = now_ms();
+        match &mut self.state {
+            State::Scanning { channel, until, .. } if now >= *until => {
+                *channel += 1;
+                *until = now + DWELL_MS;
+                let next = *channel;
+                if !self.tune(next) {
+                    if let State::Scanning { mut found, .. } = core::mem::replace(&mut self.state, State::Idle) {
+                        found.sort_by_key(|bss| -bss.signal);
+                        self.events.push_back(Event::ScanDone(found));
+                    }
+                }
             }
-
-            aggregated.extend_from_slice(&[0xAA, 0xAA, 0x03, 0x00, 0x00, 0x00]);
-            aggregated.extend_from_slice(&(frame.len() as u16).to_be_bytes());
-            aggregated.extend_from_slice(&frame);
-            
-            let padding = (4 - (frame.len() % 4)) % 4;
-            aggregated.resize(aggregated.len() + padding, 0);
-            
-            current_len = aggregated.len();
+            State::Authenticating { tries, until } if now >= *until => match *tries {
+                tries if tries < TRIES => self.authenticate(tries + 1),
+                _ => self.fail("no answer to authentication"),
+            },
+            State::Associating { tries, until } if now >= *until => match *tries {
+                tries if tries < TRIES => self.associate(tries + 1),
+                _ => self.fail("no answer to association"),
+            },
+            _ => {}
         }
-
-        aggregated
     }
 }
 
-pub struct Mac80211 {
-    pub station: Station,
-    pub tx_queues: Vec<TxQueue>,
-    pub rate_control: RateControl,
-    pub power_mgmt: PowerManagement,
-    pub csma_ca: CsmaCA,
-    pub aggregation: Aggregation,
-    pub sequence_number: AtomicU32,
-}
+impl<R: Radio> WirelessDriver for SoftMac<R> {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
 
-impl Mac80211 {
-    pub fn new(mac_addr: [u8; 6]) -> Self {
-        let mut tx_queues = Vec::new();
-        for i in 0..4 {
-            tx_queues.push(TxQueue::new(i, 256));
+    fn scan(&mut self) -> Result<(), &'static str> {
+        if !matches!(self.state, State::Idle) {
+            return Err("busy");
         }
-
-        Self {
-            station: Station::new(mac_addr),
-            tx_queues,
-            rate_control: RateControl::new(),
-            power_mgmt: PowerManagement::new(),
-            csma_ca: CsmaCA::new(),
-            aggregation: Aggregation::new(),
-            sequence_number: AtomicU32::new(0),
+        self.state = State::Scanning { channel: 0, until: now_ms() + DWELL_MS, found: Vec::new() };
+        if !self.tune(0) {
+            self.state = State::Idle;
+            return Err("cannot tune the radio");
         }
+        Ok(())
     }
 
-    pub fn start_scan(&mut self) {
-        self.station.state = StationState::Scanning;
-    }
-
-    pub fn process_beacon(&mut self, beacon: BeaconFrame, rssi: i8) {
-        if self.station.state == StationState::Scanning {
-            // Process and store beacon information
+    fn connect(&mut self, bss: &Bss, rsn: Option<&[u8]>) -> Result<(), &'static str> {
+        if matches!(self.state, State::Scanning { .. }) {
+            return Err("busy");
         }
-    }
-
-    pub fn connect(&mut self, ssid: String, bssid: [u8; 6]) {
-        self.station.ssid = Some(ssid);
-        self.station.bssid = Some(bssid);
-        self.station.state = StationState::Authenticating;
-    }
-
-    pub fn disconnect(&mut self) {
-        self.station.state = StationState::Disconnected;
-        self.station.ssid = None;
-        self.station.bssid = None;
-        self.csma_ca.reset();
-    }
-
-    pub fn create_probe_request(&self, ssid: Option<&str>) -> Vec<u8> {
-        let fc = FrameControl::new(FrameType::Management, ManagementSubtype::ProbeRequest as u8);
-        let mut frame = Vec::new();
-        
-        let header = MacHeader::new(
-            fc,
-            [0xFF; 6],
-            self.station.mac_addr,
-            [0xFF; 6],
-        );
-        
-        frame.extend_from_slice(&header.frame_control);
-        frame.extend_from_slice(&header.duration.to_le_bytes());
-        frame.extend_from_slice(&header.addr1);
-        frame.extend_from_slice(&header.addr2);
-        frame.extend_from_slice(&header.addr3);
-        frame.extend_from_slice(&header.seq_ctrl.to_le_bytes());
-        
-        frame.push(0);
-        if let Some(ssid) = ssid {
-            frame.push(ssid.len() as u8);
-            frame.extend_from_slice(ssid.as_bytes());
-        } else {
-            frame.push(0);
+        if !matches!(self.state, State::Idle) {
+            self.disconnect();
         }
-        
-        frame.push(1);
-        frame.push(8);
-        frame.extend_from_slice(&[0x02, 0x04, 0x0B, 0x16, 0x0C, 0x12, 0x18, 0x24]);
-        
-        frame
-    }
-
-    pub fn create_auth_frame(&self, bssid: [u8; 6]) -> Vec<u8> {
-        let fc = FrameControl::new(FrameType::Management, ManagementSubtype::Auth as u8);
-        let mut frame = Vec::new();
-        
-        let header = MacHeader::new(fc, bssid, self.station.mac_addr, bssid);
-        
-        frame.extend_from_slice(&header.frame_control);
-        frame.extend_from_slice(&header.duration.to_le_bytes());
-        frame.extend_from_slice(&header.addr1);
-        frame.extend_from_slice(&header.addr2);
-        frame.extend_from_slice(&header.addr3);
-        frame.extend_from_slice(&header.seq_ctrl.to_le_bytes());
-        
-        frame.extend_from_slice(&0u16.to_le_bytes());
-        frame.extend_from_slice(&1u16.to_le_bytes());
-        frame.extend_from_slice(&0u16.to_le_bytes());
-        
-        frame
-    }
-
-    pub fn create_assoc_request(&self, ssid: &str, bssid: [u8; 6]) -> Vec<u8> {
-        let fc = FrameControl::new(FrameType::Management, ManagementSubtype::AssocRequest as u8);
-        let mut frame = Vec::new();
-        
-        let header = MacHeader::new(fc, bssid, self.station.mac_addr, bssid);
-        
-        frame.extend_from_slice(&header.frame_control);
-        frame.extend_from_slice(&header.duration.to_le_bytes());
-        frame.extend_from_slice(&header.addr1);
-        frame.extend_from_slice(&header.addr2);
-        frame.extend_from_slice(&header.addr3);
-        frame.extend_from_slice(&header.seq_ctrl.to_le_bytes());
-        
-        frame.extend_from_slice(&0x31u16.to_le_bytes());
-        frame.extend_from_slice(&10u16.to_le_bytes());
-        
-        frame.push(0);
-        frame.push(ssid.len() as u8);
-        frame.extend_from_slice(ssid.as_bytes());
-        
-        frame.push(1);
-        frame.push(8);
-        frame.extend_from_slice(&[0x02, 0x04, 0x0B, 0x16, 0x0C, 0x12, 0x18, 0x24]);
-        
-        frame
+        self.radio.set_channel(bss.channel)?;
+        self.bss = Some(bss.clone());
+        self.rsn = rsn.map(<[u8]>::to_vec);
+        self.authenticate(1);
+        Ok(())
     }
 
-    pub fn get_next_sequence(&self) -> u16 {
-        let seq = self.sequence_number.fetch_add(1, Ordering::Relaxed);
-        (seq as u16) & 0x0FFF
+    fn disconnect(&mut self) {
+        if let Some(bssid) = self.bss.as_ref().map(|bss| bss.bssid) {
+            self.send_management(DEAUTHENTICATION, bssid, bssid, &REASON_LEAVING.to_le_bytes());
+        }
+        self.reset();
     }
 
-    pub fn transmit(&mut self, data: Vec<u8>, queue_id: usize) -> Result<(), ()> {
-        if queue_id >= self.tx_queues.len() {
-            return Err(());
+    fn add_key(&mut self, kind: KeyKind, key: &[u8]) -> Result<(), &'static str> {
+        match kind {
+            KeyKind::Pairwise => self.pairwise = Some(ccmp::Key::new(key, 0, 0)?),
+            KeyKind::Group { index, rsc } => {
+                let slot = self.group.get_mut(index as usize).ok_or("bad key index")?;
+                *slot = Some(ccmp::Key::new(key, index, rsc)?);
+            }
         }
-
-        self.station.tx_packets.fetch_add(1, Ordering::Relaxed);
-        self.station.tx_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
-
-        self.tx_queues[queue_id].enqueue(data)
+        Ok(())
     }
 
-    pub fn receive(&mut self, frame: &[u8]) {
-        self.station.rx_packets.fetch_add(1, Ordering::Relaxed);
-        self.station.rx_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
-        
-        if frame.len() < 24 {
-            return;
+    fn send(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        let Some(bssid) = self.bss.as_ref().map(|bss| bss.bssid) else {
+            return Err("not associated");
+        };
+        if !matches!(self.state, State::Associated) {
+            return Err("not associated");
         }
-
-        let fc = FrameControl::from_bytes([frame[0], frame[1]]);
-        
-        match fc.frame_type {
-            0 => self.handle_management_frame(frame, fc.subtype),
-            1 => self.handle_control_frame(frame, fc.subtype),
-            2 => self.handle_data_frame(frame, fc.subtype),
-            _ => {}
+        let destination = frame.header.dest_mac;
+        let protected = if self.pairwise.is_some() { PROTECTED } else { 0 };
+        let mut data = self.header(TYPE_DATA, TO_DS | protected, bssid, destination);
+
+        let mut body = Vec::with_capacity(8 + frame.payload.len());
+        body.extend_from_slice(&LLC_SNAP);
+        body.extend_from_slice(&frame.header.ethertype().to_be_bytes());
+        body.extend_from_slice(&frame.payload);
+        match &mut self.pairwise {
+            Some(key) => data.extend_from_slice(&key.encrypt(&data, &body)?),
+            None => data.extend_from_slice(&body),
         }
+        self.radio.transmit(&data)
     }
 
-    fn handle_management_frame(&mut self, frame: &[u8], subtype: u8) {
-        match subtype {
-            5 => {},
-            7 => {},
-            8 => {},
-            10 => {},
-            11 => {},
-            _ => {}
+    fn poll(&mut self) -> Option<Event> {
+        for _ in 0..RX_BUDGET {
+            let Some(frame) = self.radio.receive() else {
+                break;
+            };
+            self.receive(frame);
         }
+        self.timers();
+        self.events.pop_front()
     }
-
-    fn handle_control_frame(&mut self, _frame: &[u8], _subtype: u8) {
-    }
-
-    fn handle_data_frame(&mut self, _frame: &[u8], _subtype: u8) {
-    }
-}
\ No newline at end of file
+}
@@ -1,7 +1,384 @@
+//! Wi-Fi
+//!
+//! Each wireless adapter is a `WirelessDriver` (see `cfg80211`) and is
+//! registered here, which makes it a network interface named `wlan0`,
+//! `wlan1` and so on. This side picks the network to join from the last
+//! scan, turns a passphrase into the PMK, and once the driver has
+//! associated runs the WPA2 handshake over EAPOL frames, giving the keys it
+//! ends with back to the driver. Until the handshake is done the interface
+//! reports its link down and passes nothing but EAPOL; after, it is like
+//! any other. When it is the primary interface, the DHCP client is started
+//! on it each time it connects.
+//!
+//! `poll` drives the drivers, and runs from the main loop.
+
+pub mod ccmp;
 pub mod cfg80211;
 pub mod mac80211;
 pub mod wpa;
 
-pub use cfg80211::{Cfg80211, InterfaceType, SecurityConfig, WirelessInterface};
-pub use mac80211::{Mac80211, Band, Channel, StationState};
-pub use wpa::{WpaSupplicant, WpaVersion};
\ No newline at end of file
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub use cfg80211::{Bss, Security, WirelessDriver};
+
+use crate::net::ethernet::{EthernetController, EthernetFrame, MacAddress};
+use cfg80211::{Event, ETHERTYPE_EAPOL};
+use wpa::{Action, Supplicant};
+
+// How long a scan, joining a network, and the handshake after may take
+const SCAN_MS: u64 = 15_000;
+const ASSOCIATE_MS: u64 = 15_000;
+const HANDSHAKE_MS: u64 = 10_000;
+// Frames held for the stack before the oldest are dropped
+const RX_QUEUE: usize = 64;
+// Driver events handled per interface per poll
+const EVENT_BUDGET: usize = 32;
+
+/// Where an interface is with its network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Idle,
+    Scanning,
+    Associating,
+    /// Associated, and running the WPA2 handshake
+    Handshake,
+    Connected,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Scanning => "scanning",
+            State::Associating => "associating",
+            State::Handshake => "handshake",
+            State::Connected => "connected",
+        }
+    }
+}
+
+struct Wlan {
+    driver: Box<dyn WirelessDriver>,
+    description: String,
+    mac: MacAddress,
+    state: State,
+    /// When the scan, association or handshake under way is given up
+    deadline: u64,
+    results: Vec<Bss>,
+    /// The network asked for, and its PMK when it is secured
+    target: Option<(Bss, Option<[u8; 32]>)>,
+    supplicant: Option<Supplicant>,
+    /// Frames for the stack
+    rx: VecDeque<EthernetFrame>,
+    /// Why the last connection failed or ended
+    error: Option<&'static str>,
+}
+
+/// A wireless interface, as the ones listing them see it
+#[derive(Debug, Clone)]
+pub struct WlanInfo {
+    pub name: String,
+    pub description: String,
+    pub mac: MacAddress,
+    pub state: State,
+    /// The network joined or being joined
+    pub network: Option<Bss>,
+    pub error: Option<&'static str>,
+}
+
+// The stack's side of an interface
+struct WlanController {
+    wlan: Arc<Mutex<Wlan>>,
+    mac: MacAddress,
+}
+
+static WLANS: Mutex<BTreeMap<String, Arc<Mutex<Wlan>>>> = Mutex::new(BTreeMap::new());
+
+fn now_ms() -> u64 {
+    crate::time::clocksource::now_ns() / 1_000_000
+}
+
+impl EthernetController for WlanController {
+    fn get_mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        let mut wlan = self.wlan.lock();
+        if wlan.state != State::Connected {
+            return Err("not connected");
+        }
+        wlan.driver.send(frame)
+    }
+
+    fn receive_frame(&mut self) -> Option<EthernetFrame> {
+        self.wlan.lock().rx.pop_front()
+    }
+
+    fn set_promiscuous(&mut self, _enabled: bool) {}
+
+    fn get_link_status(&self) -> bool {
+        self.wlan.lock().state == State::Connected
+    }
+}
+
+impl Wlan {
+    fn fail(&mut self, reason: &'static str) {
+        crate::serial_println!("wifi: {}", reason);
+        self.state = State::Idle;
+        self.target = None;
+        self.supplicant = None;
+        self.error = Some(reason);
+    }
+
+    fn connected(&mut self) {
+        self.state = State::Connected;
+        self.supplicant = None;
+        if let Some((bss, _)) = &self.target {
+            crate::serial_println!("wifi: connected to {} ({})", bss.ssid_text(), bss.bssid);
+        }
+    }
+
+    fn eapol(&mut self, frame: &EthernetFrame) {
+        let Some(supplicant) = self.supplicant.as_mut() else {
+            return;
+        };
+        let actions = match supplicant.receive(&frame.payload) {
+            Ok(actions) => actions,
+            Err(e) => {
+                crate::serial_println!("wifi: EAPOL frame dropped: {}", e);
+                return;
+            }
+        };
+        let ap = frame.header.src_mac;
+        for action in actions {
+            let result = match action {
+                Action::Send(data) => self.driver.send(&EthernetFrame::new(ap, self.mac, ETHERTYPE_EAPOL, data)),
+                Action::InstallKey(kind, key) => self.driver.add_key(kind, &key),
+                Action::Complete => {
+                    self.connected();
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                self.driver.disconnect();
+                self.fail(e);
+                return;
+            }
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::ScanDone(results) => {
+                self.results = results;
+                if self.state == State::Scanning {
+                    self.state = State::Idle;
+                }
+            }
+            Event::Connected { bssid, rsn } => {
+                let Some((bss, pmk)) = &self.target else {
+                    return;
+                };
+                match pmk {
+                    Some(pmk) => {
+                        let ap_rsn = cfg80211::element(&bss.elements, cfg80211::ELEMENT_RSN).map(<[u8]>::to_vec);
+                        let rsn = rsn.unwrap_or_else(cfg80211::rsn_element);
+                        self.supplicant = Some(Supplicant::new(*pmk, self.mac, bssid, rsn, ap_rsn));
+                        self.state = State::Handshake;
+                        self.deadline = now_ms() + HANDSHAKE_MS;
+                    }
+                    None => self.connected(),
+                }
+            }
+            Event::ConnectFailed(reason) => self.fail(reason),
+            Event::Disconnected { reason } => {
+                crate::serial_println!("wifi: disconnected, reason {}", reason);
+                self.fail("disconnected by the access point");
+            }
+            Event::Frame(frame) if frame.header.ethertype() == ETHERTYPE_EAPOL => self.eapol(&frame),
+            Event::Frame(frame) => {
+                if self.state == State::Connected {
+                    if self.rx.len() == RX_QUEUE {
+                        self.rx.pop_front();
+                    }
+                    self.rx.push_back(frame);
+                }
+            }
+        }
+    }
+
+    // Run the driver; true when the interface has just come up
+    fn poll(&mut self) -> bool {
+        let was_connected = self.state == State::Connected;
+        for _ in 0..EVENT_BUDGET {
+            let Some(event) = self.driver.poll() else {
+                break;
+            };
+            self.event(event);
+        }
+        if now_ms() >= self.deadline {
+            match self.state {
+                State::Scanning => self.state = State::Idle,
+                State::Associating => {
+                    self.driver.disconnect();
+                    self.fail("no answer from the network");
+                }
+                State::Handshake => {
+                    self.driver.disconnect();
+                    self.fail("WPA2 handshake failed; is the passphrase right?");
+                }
+                _ => {}
+            }
+        }
+        !was_connected && self.state == State::Connected
+    }
+}
+
+fn wlan(name: &str) -> Result<Arc<Mutex<Wlan>>, &'static str> {
+    WLANS.lock().get(name).cloned().ok_or("no such wireless interface")
+}
+
+/// Make a wireless adapter a network interface; returns its name
+pub fn attach(driver: Box<dyn WirelessDriver>, description: String) -> String {
+    let mac = driver.mac_address();
+    let wlan = Arc::new(Mutex::new(Wlan {
+        driver,
+        description,
+        mac,
+        state: State::Idle,
+        deadline: u64::MAX,
+        results: Vec::new(),
+        target: None,
+        supplicant: None,
+        rx: VecDeque::new(),
+        error: None,
+    }));
+    let name = crate::net::interface::register("wlan", Box::new(WlanController { wlan: wlan.clone(), mac }));
+    WLANS.lock().insert(name.clone(), wlan);
+    name
+}
+
+/// Take an adapter's interface away
+pub fn detach(name: &str) {
+    crate::net::interface::unregister(name);
+    if WLANS.lock().remove(name).is_some() {
+        crate::serial_println!("wifi: {} disconnected", name);
+    }
+}
+
+pub fn interfaces() -> Vec<WlanInfo> {
+    WLANS
+        .lock()
+        .iter()
+        .map(|(name, wlan)| {
+            let wlan = wlan.lock();
+            WlanInfo {
+                name: name.clone(),
+                description: wlan.description.clone(),
+                mac: wlan.mac,
+                state: wlan.state,
+                network: wlan.target.as_ref().map(|(bss, _)| bss.clone()),
+                error: wlan.error,
+            }
+        })
+        .collect()
+}
+
+/// Start looking for networks
+pub fn scan(name: &str) -> Result<(), &'static str> {
+    let wlan = wlan(name)?;
+    let mut wlan = wlan.lock();
+    if wlan.state != State::Idle {
+        return Err("busy");
+    }
+    wlan.driver.scan()?;
+    wlan.state = State::Scanning;
+    wlan.deadline = now_ms() + SCAN_MS;
+    Ok(())
+}
+
+/// The networks the last scan found, the strongest first
+pub fn scan_results(name: &str) -> Result<Vec<Bss>, &'static str> {
+    Ok(wlan(name)?.lock().results.clone())
+}
+
+pub fn state(name: &str) -> Result<State, &'static str> {
+    Ok(wlan(name)?.lock().state)
+}
+
+/// Join the network the last scan found as `ssid`, with `passphrase` if it
+/// is secured
+pub fn connect(name: &str, ssid: &str, passphrase: Option<&str>) -> Result<(), &'static str> {
+    let wlan = wlan(name)?;
+    let mut wlan = wlan.lock();
+    if wlan.state == State::Scanning {
+        return Err("busy");
+    }
+    let bss = wlan.results.iter().find(|bss| bss.ssid == ssid.as_bytes()).cloned().ok_or("no such network")?;
+    let pmk = match bss.security() {
+        Security::Open => None,
+        Security::Wpa2Psk => Some(wpa::psk(passphrase.ok_or("the network needs a passphrase")?, &bss.ssid)?),
+        Security::Unsupported => return Err("the network's security is not supported"),
+    };
+    let rsn = pmk.map(|_| cfg80211::rsn_element());
+    wlan.driver.connect(&bss, rsn.as_deref())?;
+    wlan.state = State::Associating;
+    wlan.deadline = now_ms() + ASSOCIATE_MS;
+    wlan.target = Some((bss, pmk));
+    wlan.supplicant = None;
+    wlan.error = None;
+    wlan.rx.clear();
+    Ok(())
+}
+
+pub fn disconnect(name: &str) -> Result<(), &'static str> {
+    let wlan = wlan(name)?;
+    let mut wlan = wlan.lock();
+    wlan.driver.disconnect();
+    wlan.state = State::Idle;
+    wlan.target = None;
+    wlan.supplicant = None;
+    wlan.rx.clear();
+    Ok(())
+}
+
+/// Run the wireless drivers, and start DHCP on an interface that has just
+/// connected if it is the primary one
+pub fn poll() {
+    let wlans: Vec<(String, Arc<Mutex<Wlan>>)> =
+        WLANS.lock().iter().map(|(name, wlan)| (name.clone(), wlan.clone())).collect();
+    for (name, wlan) in wlans {
+        // DHCP sends through the interface, so it starts without the lock
+        let up = wlan.lock().poll();
+        let primary = crate::net::interface::interfaces().first().is_some_and(|interface| interface.name == name);
+        if up && primary {
+            if let Err(e) = crate::net::dhcp::restart_dhcp_client() {
+                crate::serial_println!("wifi: cannot start DHCP on {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Poll until `done` holds or `timeout_ms` has passed; true if it held
+pub fn wait(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = crate::time::clocksource::now_ns() + timeout_ms * 1_000_000;
+    loop {
+        if done() {
+            return true;
+        }
+        if crate::time::clocksource::now_ns() >= deadline {
+            return false;
+        }
+        crate::usb::urb::poll();
+        poll();
+        crate::net::interface::poll();
+        crate::net::dhcp::poll();
+        core::hint::spin_loop();
+    }
+}
@@ -380,6 +380,9 @@ impl ExceptionManager {
         serial_println!("RBX: 0x{:016X}", context.rbx);
         serial_println!("RCX: 0x{:016X}", context.rcx);
         serial_println!("RDX: 0x{:016X}", context.rdx);
+        serial_println!("Call Trace:");
+        let start = crate::debug::unwind::Frame::new(context.rip, context.rsp, context.rbp);
+        crate::debug::unwind::print_backtrace(&crate::debug::unwind::backtrace(start, crate::debug::unwind::MAX_FRAMES));
         
         // For critical exceptions, halt the system
         match exception_record.exception_code {
@@ -566,7 +569,12 @@ pub fn nt_raise_exception(
 ) -> NtStatus {
     if first_chance {
         // First chance - try user mode handlers first
-        // For kernel mode, we go straight to kernel handlers
+        // For kernel mode, we go straight to kernel handlers. The user
+        // mode dispatcher returns through NtContinue, which checks the
+        // shadow stack token left here.
+        if crate::security::mitigations::push_frame_token().is_err() {
+            return NtStatus::BadStack;
+        }
     }
     
     let _disposition = raise_exception(exception_record, context_record);
@@ -575,7 +583,12 @@ pub fn nt_raise_exception(
 
 pub fn nt_continue(context_record: &ContextRecord) -> NtStatus {
     // Restore context and continue execution
-    // In a real implementation, this would restore CPU state
+    // In a real implementation, this would restore CPU state. The shadow
+    // stack goes back to where the exception frame left it first, so a
+    // forged context cannot carry a return address past it.
+    if crate::security::mitigations::pop_frame_token().is_err() {
+        return NtStatus::BadStack;
+    }
     let _ctx = context_record;
     NtStatus::Success
 }
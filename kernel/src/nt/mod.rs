@@ -8,6 +8,7 @@ pub mod registry;
 pub mod security;
 pub mod network;
 pub mod activation;
+pub mod wdm;
 // pub mod io;
 // pub mod drivers;
 // pub mod filesystem;
@@ -1,9 +1,13 @@
 use super::NtStatus;
+use super::security::{
+    query_security_access, set_security_access, SecurityDescriptor, OBJECT_GENERIC_MAPPING,
+    OWNER_SECURITY_INFORMATION, SECURITY_MANAGER,
+};
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use core::sync::atomic::{AtomicU64, Ordering};
 
@@ -124,6 +128,7 @@ pub struct ObjectHeader {
     pub name: Option<String>,
     pub parent_directory: Option<Handle>,
     pub security_descriptor: Option<Vec<u8>>,
+    pub owner_sid: Option<super::security::Sid>,
 }
 
 impl ObjectHeader {
@@ -135,6 +140,7 @@ impl ObjectHeader {
             name: None,
             parent_directory: None,
             security_descriptor: None,
+            owner_sid: None,
         }
     }
 
@@ -217,10 +223,20 @@ impl ObjectManager {
         manager
     }
 
-    pub fn create_object<T>(&mut self, object: T) -> Handle 
+    pub fn create_object<T>(&mut self, mut object: T) -> Handle 
     where 
         T: ObjectTrait + 'static
     {
+        // New objects are owned by the creator's token user and get a
+        // descriptor built from its default DACL
+        if object.get_header().security_descriptor.is_none() {
+            if let Some(sd) = default_security_descriptor() {
+                let header = object.get_header_mut();
+                header.owner_sid = sd.owner_sid.clone();
+                header.security_descriptor = Some(sd.to_self_relative());
+            }
+        }
+        
         let handle = Handle::new();
         let object_arc = Arc::new(Mutex::new(object));
         self.objects.insert(handle, object_arc);
@@ -248,7 +264,19 @@ impl ObjectManager {
         }
     }
 
-    pub fn create_handle(&mut self, object_handle: Handle) -> Result<Handle, NtStatus> {
+    /// Check the caller's token against an object's security before granting
+    /// `desired_access` to it
+    pub fn access_object(&self, object_handle: Handle, desired_access: u32) -> Result<u32, NtStatus> {
+        let object = self.objects.get(&object_handle).ok_or(NtStatus::InvalidHandle)?;
+        let token = crate::process::current_token();
+        let object = object.lock();
+        SECURITY_MANAGER
+            .lock()
+            .object_access_check(token, object.get_header(), desired_access)
+    }
+
+    pub fn create_handle(&mut self, object_handle: Handle, desired_access: u32) -> Result<Handle, NtStatus> {
+        self.access_object(object_handle, desired_access)?;
         if let Some(object) = self.objects.get(&object_handle) {
             object.lock().get_header().add_handle();
             let handle = Handle::new();
@@ -313,6 +341,13 @@ impl ObjectTrait for ObjectDirectoryEntry {
     }
 }
 
+fn default_security_descriptor() -> Option<SecurityDescriptor> {
+    let token = crate::process::current_token();
+    let manager = SECURITY_MANAGER.lock();
+    let token = manager.get_token(token)?;
+    Some(SecurityDescriptor::create_inherited(None, None, false, token, &OBJECT_GENERIC_MAPPING))
+}
+
 lazy_static! {
     pub static ref OBJECT_MANAGER: Mutex<ObjectManager> = Mutex::new(ObjectManager::new());
 }
@@ -345,6 +380,9 @@ pub fn nt_open_directory_object(
     
     if let Some(name) = &object_attributes.object_name {
         if let Some(handle) = om.lookup_object_by_name(name) {
+            if let Err(status) = om.access_object(handle, desired_access) {
+                return status;
+            }
             *directory_handle = handle;
             return NtStatus::Success;
         }
@@ -353,6 +391,70 @@ pub fn nt_open_directory_object(
     NtStatus::ObjectNameNotFound
 }
 
+pub fn nt_query_security_object(
+    handle: Handle,
+    security_information: u32,
+    security_descriptor: &mut Vec<u8>,
+) -> NtStatus {
+    let om = OBJECT_MANAGER.lock();
+    if let Err(status) = om.access_object(handle, query_security_access(security_information)) {
+        return status;
+    }
+    let Some(object) = om.objects.get(&handle) else {
+        return NtStatus::InvalidHandle;
+    };
+    
+    let object = object.lock();
+    let sd = match &object.get_header().security_descriptor {
+        Some(bytes) => match SecurityDescriptor::from_self_relative(bytes) {
+            Ok(sd) => sd,
+            Err(status) => return status,
+        },
+        None => SecurityDescriptor::new(),
+    };
+    *security_descriptor = sd.select(security_information).to_self_relative();
+    NtStatus::Success
+}
+
+pub fn nt_set_security_object(
+    handle: Handle,
+    security_information: u32,
+    security_descriptor: &[u8],
+) -> NtStatus {
+    let new = match SecurityDescriptor::from_self_relative(security_descriptor) {
+        Ok(sd) => sd,
+        Err(status) => return status,
+    };
+    
+    let om = OBJECT_MANAGER.lock();
+    if let Err(status) = om.access_object(handle, set_security_access(security_information)) {
+        return status;
+    }
+    if security_information & OWNER_SECURITY_INFORMATION != 0 {
+        let Some(owner) = &new.owner_sid else {
+            return NtStatus::InvalidOwner;
+        };
+        let token = crate::process::current_token();
+        if let Err(status) = SECURITY_MANAGER.lock().validate_owner(token, owner) {
+            return status;
+        }
+    }
+    let Some(object) = om.objects.get(&handle) else {
+        return NtStatus::InvalidHandle;
+    };
+    
+    let mut object = object.lock();
+    let header = object.get_header_mut();
+    let mut sd = match &header.security_descriptor {
+        Some(bytes) => SecurityDescriptor::from_self_relative(bytes).unwrap_or_else(|_| SecurityDescriptor::new()),
+        None => SecurityDescriptor::new(),
+    };
+    sd.merge(security_information, &new);
+    header.owner_sid = sd.owner_sid.clone();
+    header.security_descriptor = Some(sd.to_self_relative());
+    NtStatus::Success
+}
+
 pub fn nt_close(handle: Handle) -> NtStatus {
     let mut om = OBJECT_MANAGER.lock();
     om.close_handle(handle)
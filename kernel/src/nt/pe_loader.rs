@@ -181,6 +181,9 @@ pub const IMAGE_SUBSYSTEM_WINDOWS_GUI: u16 = 2;
 pub const IMAGE_SUBSYSTEM_WINDOWS_CUI: u16 = 3;
 
 // Section characteristics
+pub const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+pub const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+
 pub const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
 pub const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
 pub const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x00000080;
@@ -447,6 +450,16 @@ impl PeLoader {
         let preferred_base = VirtAddr::new(pe_info.optional_header.image_base);
         let size = pe_info.optional_header.size_of_image as u64;
         
+        // Relocatable images go wherever ASLR puts them
+        let reloc_dir = &pe_info.optional_header.data_directory[IMAGE_DIRECTORY_ENTRY_BASERELOC];
+        let relocatable = reloc_dir.size != 0
+            && pe_info.file_header.characteristics & IMAGE_FILE_RELOCS_STRIPPED == 0
+            && pe_info.optional_header.dll_characteristics & IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE != 0;
+        if relocatable && crate::security::aslr::is_enabled() {
+            let base = crate::security::aslr::randomize_image_base(preferred_base.as_u64(), size, true);
+            return Ok(VirtAddr::new(base));
+        }
+        
         // Try preferred base first
         let base_address = if preferred_base.as_u64() >= 0x10000000 {
             preferred_base
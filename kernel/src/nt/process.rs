@@ -5,7 +5,7 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use core::sync::atomic::{AtomicU64, AtomicU32, Ordering};
@@ -149,6 +149,14 @@ impl RegistryValue {
         }
     }
     
+    pub fn as_binary(&self) -> Option<&[u8]> {
+        if self.value_type == RegistryValueType::RegBinary {
+            Some(&self.data)
+        } else {
+            None
+        }
+    }
+    
     pub fn as_qword(&self) -> Option<u64> {
         if self.value_type == RegistryValueType::RegQword && self.data.len() >= 8 {
             let mut bytes = [0u8; 8];
@@ -269,6 +277,48 @@ impl PredefinedKey {
     }
 }
 
+// Hives readable only by the SYSTEM account
+const PROTECTED_HIVES: &[&str] = &["SAM", "SECURITY"];
+
+/// Full path of a key named the Win32 way (HKLM\Software) or by its NT
+/// path (\Registry\Machine\Software)
+fn full_key_path(path: &str) -> Option<String> {
+    let mut parts = path.split('\\').filter(|p| !p.is_empty());
+    let root = match parts.next()? {
+        "HKEY_LOCAL_MACHINE" | "HKLM" => PredefinedKey::HkeyLocalMachine.to_path(),
+        "HKEY_CURRENT_USER" | "HKCU" => PredefinedKey::HkeyCurrentUser.to_path(),
+        "HKEY_CLASSES_ROOT" | "HKCR" => PredefinedKey::HkeyClassesRoot.to_path(),
+        "HKEY_USERS" | "HKU" => PredefinedKey::HkeyUsers.to_path(),
+        "Registry" => "\\Registry",
+        _ => return None,
+    };
+    let mut full = root.to_string();
+    for part in parts {
+        full.push('\\');
+        full.push_str(part);
+    }
+    Some(full)
+}
+
+/// Whether a path lies in a hive reserved for the SYSTEM account
+pub fn is_protected_path(path: &str) -> bool {
+    let Some(full) = full_key_path(path) else {
+        return false;
+    };
+    let mut parts = full.split('\\').filter(|p| !p.is_empty());
+    parts.next() == Some("Registry")
+        && parts.next() == Some("Machine")
+        && parts.next().is_some_and(|hive| PROTECTED_HIVES.iter().any(|h| h.eq_ignore_ascii_case(hive)))
+}
+
+fn caller_is_system() -> bool {
+    let token = crate::process::current_token();
+    super::security::SECURITY_MANAGER
+        .lock()
+        .get_token(token)
+        .is_some_and(|token| token.is_system())
+}
+
 // Registry manager
 pub struct RegistryManager {
     keys: BTreeMap<Handle, RegistryKey>,
@@ -446,6 +496,9 @@ impl RegistryManager {
     }
     
     fn create_key_internal(&mut self, name: String, path: String, parent: Option<Handle>) -> Result<Handle, NtStatus> {
+        if let Some(&existing) = self.path_to_handle.get(&path) {
+            return Ok(existing);
+        }
         let handle = Handle::new();
         let mut key = RegistryKey::new(name.clone(), path.clone());
         key.parent = parent;
@@ -602,6 +655,66 @@ impl RegistryManager {
     pub fn get_predefined_key(&self, predefined: PredefinedKey) -> Option<Handle> {
         self.predefined_keys.get(&predefined).copied()
     }
+    
+    // Subkey of a key by name, matched without regard to case as Windows does
+    fn find_subkey(&self, parent: Handle, name: &str) -> Option<Handle> {
+        let key = self.keys.get(&parent)?;
+        key.subkeys.iter().find(|(subkey, _)| subkey.eq_ignore_ascii_case(name)).map(|(_, &handle)| handle)
+    }
+    
+    fn find_key(&self, path: &str) -> Option<Handle> {
+        let full = full_key_path(path)?;
+        let mut parts = full.split('\\').filter(|p| !p.is_empty());
+        let mut handle = *self.path_to_handle.get(&format!("\\{}", parts.next()?))?;
+        for part in parts {
+            handle = self.find_subkey(handle, part)?;
+        }
+        Some(handle)
+    }
+    
+    pub fn get_key_by_path(&self, path: &str) -> Option<&RegistryKey> {
+        self.keys.get(&self.find_key(path)?)
+    }
+    
+    /// Open a key for writing, creating any missing keys along the path
+    pub fn create_key_by_path(&mut self, path: &str) -> Option<&mut RegistryKey> {
+        let full = full_key_path(path)?;
+        let mut parts = full.split('\\').filter(|p| !p.is_empty());
+        let root = parts.next()?;
+        let mut handle = self.create_key_internal(root.to_string(), format!("\\{}", root), None).ok()?;
+        for part in parts {
+            handle = match self.find_subkey(handle, part) {
+                Some(subkey) => subkey,
+                None => {
+                    let path = format!("{}\\{}", self.keys.get(&handle)?.path, part);
+                    self.create_key_internal(part.to_string(), path, Some(handle)).ok()?
+                }
+            };
+        }
+        self.keys.get_mut(&handle)
+    }
+    
+    /// Delete a key along with every key below it
+    pub fn delete_key_by_path(&mut self, path: &str) -> bool {
+        let Some(handle) = self.find_key(path) else {
+            return false;
+        };
+        self.delete_tree(handle);
+        true
+    }
+    
+    fn delete_tree(&mut self, handle: Handle) {
+        let Some(key) = self.keys.remove(&handle) else {
+            return;
+        };
+        self.path_to_handle.remove(&key.path);
+        if let Some(parent) = key.parent.and_then(|parent| self.keys.get_mut(&parent)) {
+            parent.remove_subkey(&key.name);
+        }
+        for child in key.subkeys.into_values() {
+            self.delete_tree(child);
+        }
+    }
 }
 
 // Global registry manager
@@ -626,6 +739,9 @@ pub fn nt_create_key(
     options: RegistryOptions,
     disposition: &mut RegistryDisposition,
 ) -> NtStatus {
+    if is_protected_path(&format!("\\Registry\\{}", object_attributes)) && !caller_is_system() {
+        return NtStatus::AccessDenied;
+    }
     let mut manager = REGISTRY_MANAGER.lock();
     
     match manager.create_key(None, object_attributes, options, desired_access) {
@@ -643,6 +759,9 @@ pub fn nt_open_key(
     desired_access: RegistryRights,
     object_attributes: &str,
 ) -> NtStatus {
+    if is_protected_path(&format!("\\Registry\\{}", object_attributes)) && !caller_is_system() {
+        return NtStatus::AccessDenied;
+    }
     let manager = REGISTRY_MANAGER.lock();
     
     match manager.open_key(None, object_attributes) {
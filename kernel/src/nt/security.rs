@@ -4,7 +4,7 @@ use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::{vec, format};
 use alloc::boxed::Box;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use core::sync::atomic::{AtomicU32, Ordering};
 
@@ -46,6 +46,41 @@ impl Sid {
         result
     }
     
+    /// Length of the binary SID in bytes
+    pub fn length(&self) -> usize {
+        8 + 4 * self.sub_authorities.len()
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length());
+        bytes.push(self.revision);
+        bytes.push(self.sub_authorities.len() as u8);
+        bytes.extend_from_slice(&self.identifier_authority);
+        for sub_auth in &self.sub_authorities {
+            bytes.extend_from_slice(&sub_auth.to_le_bytes());
+        }
+        bytes
+    }
+    
+    pub fn from_bytes(data: &[u8]) -> Result<Self, NtStatus> {
+        if data.len() < 8 || data[0] != 1 {
+            return Err(NtStatus::InvalidSid);
+        }
+        let count = data[1] as usize;
+        if count > 15 || data.len() < 8 + 4 * count {
+            return Err(NtStatus::InvalidSid);
+        }
+        
+        let mut identifier_authority = [0u8; 6];
+        identifier_authority.copy_from_slice(&data[2..8]);
+        let sub_authorities = data[8..8 + 4 * count]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        
+        Ok(Self::new(data[0], identifier_authority, sub_authorities))
+    }
+    
     pub fn from_string(sid_string: &str) -> Result<Self, NtStatus> {
         if !sid_string.starts_with("S-") {
             return Err(NtStatus::InvalidSid);
@@ -152,6 +187,21 @@ pub enum AceType {
     SystemScopedPolicyId = 19,
 }
 
+impl AceType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => AceType::AccessAllowed,
+            1 => AceType::AccessDenied,
+            2 => AceType::SystemAudit,
+            3 => AceType::SystemAlarm,
+            17 => AceType::SystemMandatoryLabel,
+            // Object, callback and compound ACEs carry extra data this
+            // implementation does not model
+            _ => return None,
+        })
+    }
+}
+
 // ACE flags
 bitflags::bitflags! {
     #[repr(transparent)]
@@ -197,6 +247,40 @@ impl Ace {
     pub fn audit(access_mask: u32, sid: Sid, flags: AceFlags) -> Self {
         Self::new(AceType::SystemAudit, flags, access_mask, sid)
     }
+    
+    pub fn is_inherit_only(&self) -> bool {
+        self.ace_flags.contains(AceFlags::INHERIT_ONLY_ACE)
+    }
+    
+    pub fn length(&self) -> usize {
+        8 + self.sid.length()
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length());
+        bytes.push(self.ace_type as u8);
+        bytes.push(self.ace_flags.bits());
+        bytes.extend_from_slice(&(self.length() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.access_mask.to_le_bytes());
+        bytes.extend_from_slice(&self.sid.to_bytes());
+        bytes
+    }
+    
+    /// Parse one ACE, returning it with its on-disk size
+    pub fn from_bytes(data: &[u8]) -> Result<(Self, usize), NtStatus> {
+        if data.len() < 8 {
+            return Err(NtStatus::InvalidAcl);
+        }
+        let size = u16::from_le_bytes([data[2], data[3]]) as usize;
+        if size < 8 || size > data.len() {
+            return Err(NtStatus::InvalidAcl);
+        }
+        let ace_type = AceType::from_u8(data[0]).ok_or(NtStatus::InvalidAcl)?;
+        let access_mask = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let sid = Sid::from_bytes(&data[8..size])?;
+        
+        Ok((Self::new(ace_type, AceFlags::from_bits_truncate(data[1]), access_mask, sid), size))
+    }
 }
 
 // Access Control List (ACL)
@@ -253,6 +337,103 @@ impl Acl {
         // Check if all desired permissions are allowed
         (allowed & desired_access) == desired_access
     }
+    
+    pub fn length(&self) -> usize {
+        8 + self.aces.iter().map(Ace::length).sum::<usize>()
+    }
+    
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length());
+        bytes.push(self.revision);
+        bytes.push(0);
+        bytes.extend_from_slice(&(self.length() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.aces.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        for ace in &self.aces {
+            bytes.extend_from_slice(&ace.to_bytes());
+        }
+        bytes
+    }
+    
+    pub fn from_bytes(data: &[u8]) -> Result<Self, NtStatus> {
+        if data.len() < 8 || !(2..=4).contains(&data[0]) {
+            return Err(NtStatus::InvalidAcl);
+        }
+        let size = u16::from_le_bytes([data[2], data[3]]) as usize;
+        let count = u16::from_le_bytes([data[4], data[5]]) as usize;
+        if size < 8 || size > data.len() {
+            return Err(NtStatus::InvalidAcl);
+        }
+        
+        let mut acl = Self { revision: data[0], aces: Vec::with_capacity(count) };
+        let mut offset = 8;
+        for _ in 0..count {
+            let (ace, ace_size) = Ace::from_bytes(&data[offset..size])?;
+            acl.aces.push(ace);
+            offset += ace_size;
+        }
+        Ok(acl)
+    }
+    
+    /// ACEs a new child object inherits from this ACL.
+    ///
+    /// CREATOR OWNER/CREATOR GROUP are replaced with the child's owner and
+    /// group, generic rights in effective ACEs are mapped to specific rights,
+    /// and containers keep inherit-only copies so inheritance continues to
+    /// their own children.
+    pub fn inherit(
+        &self,
+        is_container: bool,
+        owner: &Sid,
+        group: &Sid,
+        mapping: &GenericMapping,
+    ) -> Option<Acl> {
+        let inherit_flags = AceFlags::OBJECT_INHERIT_ACE | AceFlags::CONTAINER_INHERIT_ACE;
+        let audit_flags = AceFlags::SUCCESSFUL_ACCESS_ACE_FLAG | AceFlags::FAILED_ACCESS_ACE_FLAG;
+        let creator_owner = WellKnownSids::creator_owner_sid();
+        let creator_group = WellKnownSids::creator_group_sid();
+        let mut acl = Acl::new();
+        
+        for ace in &self.aces {
+            let object_inherit = ace.ace_flags.contains(AceFlags::OBJECT_INHERIT_ACE);
+            let container_inherit = ace.ace_flags.contains(AceFlags::CONTAINER_INHERIT_ACE);
+            let no_propagate = ace.ace_flags.contains(AceFlags::NO_PROPAGATE_INHERIT_ACE);
+            
+            let applies = if is_container { container_inherit } else { object_inherit };
+            let propagates = is_container && !no_propagate && (object_inherit || container_inherit);
+            if !applies && !propagates {
+                continue;
+            }
+            
+            let is_creator = ace.sid == creator_owner || ace.sid == creator_group;
+            if applies {
+                let sid = if ace.sid == creator_owner {
+                    owner.clone()
+                } else if ace.sid == creator_group {
+                    group.clone()
+                } else {
+                    ace.sid.clone()
+                };
+                let mut flags = AceFlags::INHERITED_ACE | (ace.ace_flags & audit_flags);
+                if propagates && !is_creator {
+                    flags |= ace.ace_flags & inherit_flags;
+                }
+                acl.add_ace(Ace::new(ace.ace_type, flags, map_generic(ace.access_mask, mapping), sid));
+            }
+            if propagates && (is_creator || !applies) {
+                let flags = AceFlags::INHERITED_ACE
+                    | AceFlags::INHERIT_ONLY_ACE
+                    | (ace.ace_flags & (inherit_flags | audit_flags));
+                acl.add_ace(Ace::new(ace.ace_type, flags, ace.access_mask, ace.sid.clone()));
+            }
+        }
+        
+        if acl.aces.is_empty() {
+            None
+        } else {
+            Some(acl)
+        }
+    }
 }
 
 // Security Descriptor
@@ -320,6 +501,297 @@ impl SecurityDescriptor {
         self.control.insert(SecurityDescriptorControl::SE_SACL_PRESENT);
         self.control.remove(SecurityDescriptorControl::SE_SACL_DEFAULTED);
     }
+    
+    /// Serialize to the self-relative format stored on disk and returned by
+    /// GetFileSecurity
+    pub fn to_self_relative(&self) -> Vec<u8> {
+        let mut control = self.control | SecurityDescriptorControl::SE_SELF_RELATIVE;
+        control.set(SecurityDescriptorControl::SE_DACL_PRESENT, self.dacl.is_some());
+        control.set(SecurityDescriptorControl::SE_SACL_PRESENT, self.sacl.is_some());
+        
+        let mut bytes = vec![0u8; 20];
+        bytes[0] = self.revision;
+        bytes[2..4].copy_from_slice(&control.bits().to_le_bytes());
+        
+        let append = |bytes: &mut Vec<u8>, field: usize, data: Vec<u8>| {
+            let offset = bytes.len() as u32;
+            bytes[field..field + 4].copy_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&data);
+        };
+        if let Some(sacl) = &self.sacl {
+            append(&mut bytes, 12, sacl.to_bytes());
+        }
+        if let Some(dacl) = &self.dacl {
+            append(&mut bytes, 16, dacl.to_bytes());
+        }
+        if let Some(owner) = &self.owner_sid {
+            append(&mut bytes, 4, owner.to_bytes());
+        }
+        if let Some(group) = &self.group_sid {
+            append(&mut bytes, 8, group.to_bytes());
+        }
+        bytes
+    }
+    
+    pub fn from_self_relative(data: &[u8]) -> Result<Self, NtStatus> {
+        if data.len() < 20 {
+            return Err(NtStatus::InvalidSecurityDescr);
+        }
+        if data[0] != 1 {
+            return Err(NtStatus::UnknownRevision);
+        }
+        let control = SecurityDescriptorControl::from_bits_truncate(u16::from_le_bytes([data[2], data[3]]));
+        if !control.contains(SecurityDescriptorControl::SE_SELF_RELATIVE) {
+            return Err(NtStatus::InvalidSecurityDescr);
+        }
+        
+        let offset = |field: usize| {
+            u32::from_le_bytes([data[field], data[field + 1], data[field + 2], data[field + 3]]) as usize
+        };
+        let section = |field: usize| -> Result<Option<&[u8]>, NtStatus> {
+            match offset(field) {
+                0 => Ok(None),
+                start if start < data.len() => Ok(Some(&data[start..])),
+                _ => Err(NtStatus::InvalidSecurityDescr),
+            }
+        };
+        
+        let owner_sid = section(4)?.map(Sid::from_bytes).transpose()?;
+        let group_sid = section(8)?.map(Sid::from_bytes).transpose()?;
+        let sacl = match section(12)? {
+            Some(acl) if control.contains(SecurityDescriptorControl::SE_SACL_PRESENT) => Some(Acl::from_bytes(acl)?),
+            _ => None,
+        };
+        let dacl = match section(16)? {
+            Some(acl) if control.contains(SecurityDescriptorControl::SE_DACL_PRESENT) => Some(Acl::from_bytes(acl)?),
+            _ => None,
+        };
+        
+        Ok(Self {
+            revision: data[0],
+            control: control - SecurityDescriptorControl::SE_SELF_RELATIVE,
+            owner_sid,
+            group_sid,
+            dacl,
+            sacl,
+        })
+    }
+    
+    /// Build the descriptor for a new object.
+    ///
+    /// The owner and primary group come from the creator's token. Unless the
+    /// creator supplies an explicit DACL, ACEs are inherited from the parent
+    /// container, falling back to the token's default DACL.
+    pub fn create_inherited(
+        parent: Option<&SecurityDescriptor>,
+        explicit: Option<&SecurityDescriptor>,
+        is_container: bool,
+        token: &Token,
+        mapping: &GenericMapping,
+    ) -> Self {
+        let mut sd = Self::new();
+        let owner = explicit
+            .and_then(|sd| sd.owner_sid.clone())
+            .unwrap_or_else(|| token.owner_sid.clone());
+        let group = explicit
+            .and_then(|sd| sd.group_sid.clone())
+            .unwrap_or_else(|| token.primary_group.clone());
+        
+        let explicit_dacl = explicit.filter(|sd| sd.dacl.is_some());
+        let inherited_dacl = parent
+            .and_then(|parent| parent.dacl.as_ref())
+            .and_then(|dacl| dacl.inherit(is_container, &owner, &group, mapping));
+        match (explicit_dacl, inherited_dacl) {
+            (Some(explicit), inherited) => {
+                let mut dacl = explicit.dacl.clone().unwrap_or_else(Acl::new);
+                if explicit.control.contains(SecurityDescriptorControl::SE_DACL_PROTECTED) {
+                    sd.control.insert(SecurityDescriptorControl::SE_DACL_PROTECTED);
+                } else if let Some(inherited) = inherited {
+                    dacl.aces.extend(inherited.aces);
+                }
+                sd.set_dacl(dacl);
+            }
+            (None, Some(inherited)) => {
+                sd.set_dacl(inherited);
+                sd.control.insert(SecurityDescriptorControl::SE_DACL_AUTO_INHERITED);
+            }
+            (None, None) => {
+                sd.set_dacl(token.default_dacl.clone().unwrap_or_else(|| {
+                    let mut dacl = Acl::new();
+                    dacl.add_ace(Ace::allow(mapping.generic_all, owner.clone()));
+                    dacl.add_ace(Ace::allow(mapping.generic_all, WellKnownSids::system_sid()));
+                    dacl
+                }));
+                sd.control.insert(SecurityDescriptorControl::SE_DACL_DEFAULTED);
+            }
+        }
+        
+        let explicit_sacl = explicit.and_then(|sd| sd.sacl.clone());
+        let inherited_sacl = parent
+            .and_then(|parent| parent.sacl.as_ref())
+            .and_then(|sacl| sacl.inherit(is_container, &owner, &group, mapping));
+        if let Some(sacl) = explicit_sacl.or(inherited_sacl) {
+            sd.set_sacl(sacl);
+        }
+        
+        sd.set_owner(owner);
+        sd.set_group(group);
+        sd
+    }
+    
+    /// Copy of the parts selected by `security_information`
+    pub fn select(&self, security_information: u32) -> Self {
+        let mut sd = Self::new();
+        if security_information & OWNER_SECURITY_INFORMATION != 0 {
+            sd.owner_sid = self.owner_sid.clone();
+        }
+        if security_information & GROUP_SECURITY_INFORMATION != 0 {
+            sd.group_sid = self.group_sid.clone();
+        }
+        if security_information & DACL_SECURITY_INFORMATION != 0 {
+            sd.dacl = self.dacl.clone();
+            sd.control |= self.control & SecurityDescriptorControl::SE_DACL_PROTECTED;
+        }
+        if security_information & SACL_SECURITY_INFORMATION != 0 {
+            sd.sacl = self.sacl.clone();
+            sd.control |= self.control & SecurityDescriptorControl::SE_SACL_PROTECTED;
+        }
+        sd
+    }
+    
+    /// Replace the parts selected by `security_information` with those of
+    /// `new`
+    pub fn merge(&mut self, security_information: u32, new: &SecurityDescriptor) {
+        if security_information & OWNER_SECURITY_INFORMATION != 0 {
+            self.owner_sid = new.owner_sid.clone();
+        }
+        if security_information & GROUP_SECURITY_INFORMATION != 0 {
+            self.group_sid = new.group_sid.clone();
+        }
+        if security_information & DACL_SECURITY_INFORMATION != 0 {
+            self.dacl = new.dacl.clone();
+            let protected = security_information & PROTECTED_DACL_SECURITY_INFORMATION != 0
+                || (security_information & UNPROTECTED_DACL_SECURITY_INFORMATION == 0
+                    && new.control.contains(SecurityDescriptorControl::SE_DACL_PROTECTED));
+            self.control.set(SecurityDescriptorControl::SE_DACL_PROTECTED, protected);
+        }
+        if security_information & SACL_SECURITY_INFORMATION != 0 {
+            self.sacl = new.sacl.clone();
+        }
+    }
+}
+
+/// Access a caller needs to read the parts of a descriptor selected by
+/// `security_information`
+pub fn query_security_access(security_information: u32) -> u32 {
+    let mut access = 0;
+    if security_information & (OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION) != 0 {
+        access |= READ_CONTROL;
+    }
+    if security_information & SACL_SECURITY_INFORMATION != 0 {
+        access |= ACCESS_SYSTEM_SECURITY;
+    }
+    access
+}
+
+/// Access a caller needs to change the parts of a descriptor selected by
+/// `security_information`
+pub fn set_security_access(security_information: u32) -> u32 {
+    let mut access = 0;
+    if security_information & (OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION) != 0 {
+        access |= WRITE_OWNER;
+    }
+    if security_information & DACL_SECURITY_INFORMATION != 0 {
+        access |= WRITE_DAC;
+    }
+    if security_information & SACL_SECURITY_INFORMATION != 0 {
+        access |= ACCESS_SYSTEM_SECURITY;
+    }
+    access
+}
+
+fn map_generic(access: u32, mapping: &GenericMapping) -> u32 {
+    let mut specific = access & !(GENERIC_READ | GENERIC_WRITE | GENERIC_EXECUTE | GENERIC_ALL);
+    
+    if access & GENERIC_READ != 0 {
+        specific |= mapping.generic_read;
+    }
+    if access & GENERIC_WRITE != 0 {
+        specific |= mapping.generic_write;
+    }
+    if access & GENERIC_EXECUTE != 0 {
+        specific |= mapping.generic_execute;
+    }
+    if access & GENERIC_ALL != 0 {
+        specific |= mapping.generic_all;
+    }
+    
+    specific
+}
+
+/// The NT access check: walks the DACL in order, accumulating granted and
+/// denied rights for ACEs whose SID is in the token, until every requested
+/// right is decided. Returns the granted access mask.
+fn check_token_access(
+    token: &Token,
+    sd: &SecurityDescriptor,
+    desired_access: u32,
+    mapping: &GenericMapping,
+) -> Result<u32, NtStatus> {
+    let mut desired = map_generic(desired_access, mapping);
+    let maximum_allowed = desired & MAXIMUM_ALLOWED != 0;
+    desired &= !MAXIMUM_ALLOWED;
+    let mut granted = 0u32;
+    
+    if desired & ACCESS_SYSTEM_SECURITY != 0 {
+        if !token.has_privilege(Privilege::Security) {
+            return Err(NtStatus::PrivilegeNotHeld);
+        }
+        granted |= ACCESS_SYSTEM_SECURITY;
+    }
+    if desired & WRITE_OWNER != 0 && token.has_privilege(Privilege::TakeOwnership) {
+        granted |= WRITE_OWNER;
+    }
+    // The owner can always read and rewrite the DACL
+    if let Some(owner) = &sd.owner_sid {
+        if token.is_member(owner) {
+            granted |= READ_CONTROL | WRITE_DAC;
+        }
+    }
+    
+    match &sd.dacl {
+        // A NULL DACL grants everything
+        None => granted |= mapping.generic_all | STANDARD_RIGHTS_ALL | desired,
+        Some(dacl) => {
+            let mut denied = 0u32;
+            for ace in &dacl.aces {
+                if ace.is_inherit_only() || !token.is_member(&ace.sid) {
+                    continue;
+                }
+                let mask = map_generic(ace.access_mask, mapping);
+                match ace.ace_type {
+                    AceType::AccessAllowed => granted |= mask & !denied,
+                    AceType::AccessDenied => denied |= mask & !granted,
+                    _ => {}
+                }
+                if !maximum_allowed && desired & !granted == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    
+    if desired & !granted != 0 {
+        return Err(NtStatus::AccessDenied);
+    }
+    if maximum_allowed {
+        if granted == 0 {
+            return Err(NtStatus::AccessDenied);
+        }
+        Ok(granted)
+    } else {
+        Ok(desired)
+    }
 }
 
 // Privilege definitions
@@ -508,6 +980,20 @@ impl Token {
         token
     }
     
+    pub fn is_member(&self, sid: &Sid) -> bool {
+        self.user_sid == *sid || self.groups.iter().any(|(group, attributes)| {
+            group == sid && attributes & SE_GROUP_ENABLED != 0
+        })
+    }
+    
+    pub fn is_system(&self) -> bool {
+        self.user_sid == WellKnownSids::system_sid()
+    }
+    
+    pub fn is_admin(&self) -> bool {
+        self.is_system() || self.is_member(&WellKnownSids::administrators_sid())
+    }
+    
     pub fn has_privilege(&self, privilege: Privilege) -> bool {
         let luid = Luid::new(privilege as u64);
         self.privileges.iter().any(|p| {
@@ -551,14 +1037,22 @@ impl ObjectTrait for Token {
 // Security Manager
 pub struct SecurityManager {
     tokens: BTreeMap<Handle, Token>,
+    system_token: Handle,
     next_luid: AtomicU32,
     audit_enabled: bool,
 }
 
 impl SecurityManager {
     pub fn new() -> Self {
+        // The SYSTEM token exists from the start so kernel processes created
+        // before security initialization still get a primary token
+        let mut tokens = BTreeMap::new();
+        let system_token = Handle::new();
+        tokens.insert(system_token, Token::create_system_token());
+        
         Self {
-            tokens: BTreeMap::new(),
+            tokens,
+            system_token,
             next_luid: AtomicU32::new(1000),
             audit_enabled: false,
         }
@@ -568,18 +1062,55 @@ impl SecurityManager {
         use crate::serial_println;
         
         serial_println!("Security: Initializing Windows security subsystem");
-        
-        // Create system token
-        let system_token = Token::create_system_token();
-        let system_handle = Handle::new();
-        self.tokens.insert(system_handle, system_token);
-        
-        serial_println!("Security: Created SYSTEM token");
+        serial_println!("Security: SYSTEM token is {:?}", self.system_token);
         serial_println!("Security: Security subsystem initialized");
         
         NtStatus::Success
     }
     
+    pub fn system_token(&self) -> Handle {
+        self.system_token
+    }
+    
+    pub fn get_token(&self, token_handle: Handle) -> Option<&Token> {
+        self.tokens.get(&token_handle)
+    }
+    
+    pub fn close_token(&mut self, token_handle: Handle) -> NtStatus {
+        if token_handle == self.system_token {
+            return NtStatus::AccessDenied;
+        }
+        match self.tokens.remove(&token_handle) {
+            Some(_) => NtStatus::Success,
+            None => NtStatus::InvalidHandle,
+        }
+    }
+    
+    /// Access check against an object's security descriptor. Objects without
+    /// one fall back to ownership: SYSTEM, administrators and the object's
+    /// owner get the requested access, everyone else is denied.
+    pub fn object_access_check(
+        &self,
+        token_handle: Handle,
+        header: &ObjectHeader,
+        desired_access: u32,
+    ) -> Result<u32, NtStatus> {
+        let token = self.tokens.get(&token_handle)
+            .ok_or(NtStatus::InvalidHandle)?;
+        
+        if let Some(bytes) = &header.security_descriptor {
+            let sd = SecurityDescriptor::from_self_relative(bytes)?;
+            return check_token_access(token, &sd, desired_access, &OBJECT_GENERIC_MAPPING);
+        }
+        
+        match &header.owner_sid {
+            None => Ok(desired_access),
+            Some(_) if token.is_admin() => Ok(desired_access),
+            Some(owner) if token.is_member(owner) => Ok(desired_access),
+            Some(_) => Err(NtStatus::AccessDenied),
+        }
+    }
+    
     pub fn create_token(
         &mut self,
         token_type: TokenType,
@@ -637,50 +1168,47 @@ impl SecurityManager {
         let token = self.tokens.get(&token_handle)
             .ok_or(NtStatus::InvalidHandle)?;
         
-        // Map generic rights to specific rights
-        let specific_access = self.map_generic_access(desired_access, generic_mapping);
-        
-        // Check owner - owner always has full access
-        if let Some(ref owner_sid) = security_descriptor.owner_sid {
-            if *owner_sid == token.user_sid {
-                return Ok(specific_access);
-            }
-        }
-        
-        // Check DACL
-        if let Some(ref dacl) = security_descriptor.dacl {
-            if dacl.check_access(&token.user_sid, specific_access) {
-                return Ok(specific_access);
-            }
-            
-            // Check group SIDs
-            for (group_sid, _) in &token.groups {
-                if dacl.check_access(group_sid, specific_access) {
-                    return Ok(specific_access);
-                }
-            }
-        }
-        
-        Err(NtStatus::AccessDenied)
+        check_token_access(token, security_descriptor, desired_access, generic_mapping)
     }
     
-    fn map_generic_access(&self, access: u32, mapping: &GenericMapping) -> u32 {
-        let mut specific = access;
+    /// Whether the SACL asks for an audit record of this access attempt
+    pub fn audit_required(
+        &self,
+        security_descriptor: &SecurityDescriptor,
+        token_handle: Handle,
+        access: u32,
+        generic_mapping: &GenericMapping,
+        success: bool,
+    ) -> bool {
+        let (Some(token), Some(sacl)) = (self.tokens.get(&token_handle), &security_descriptor.sacl) else {
+            return false;
+        };
+        let outcome = if success {
+            AceFlags::SUCCESSFUL_ACCESS_ACE_FLAG
+        } else {
+            AceFlags::FAILED_ACCESS_ACE_FLAG
+        };
+        let access = map_generic(access, generic_mapping);
         
-        if access & GENERIC_READ != 0 {
-            specific = (specific & !GENERIC_READ) | mapping.generic_read;
-        }
-        if access & GENERIC_WRITE != 0 {
-            specific = (specific & !GENERIC_WRITE) | mapping.generic_write;
-        }
-        if access & GENERIC_EXECUTE != 0 {
-            specific = (specific & !GENERIC_EXECUTE) | mapping.generic_execute;
-        }
-        if access & GENERIC_ALL != 0 {
-            specific = (specific & !GENERIC_ALL) | mapping.generic_all;
+        sacl.aces.iter().any(|ace| {
+            ace.ace_type == AceType::SystemAudit
+                && !ace.is_inherit_only()
+                && ace.ace_flags.contains(outcome)
+                && map_generic(ace.access_mask, generic_mapping) & access != 0
+                && token.is_member(&ace.sid)
+        })
+    }
+    
+    /// A new owner must be the caller or one of its groups unless the caller
+    /// holds SeRestorePrivilege
+    pub fn validate_owner(&self, token_handle: Handle, owner: &Sid) -> Result<(), NtStatus> {
+        let token = self.tokens.get(&token_handle)
+            .ok_or(NtStatus::InvalidHandle)?;
+        if token.is_member(owner) || token.has_privilege(Privilege::Restore) {
+            Ok(())
+        } else {
+            Err(NtStatus::InvalidOwner)
         }
-        
-        specific
     }
     
     pub fn get_token_user(&self, token_handle: Handle) -> Result<Sid, NtStatus> {
@@ -730,7 +1258,14 @@ impl SecurityManager {
     }
 }
 
+// Group attributes
+pub const SE_GROUP_MANDATORY: u32 = 0x00000001;
+pub const SE_GROUP_ENABLED_BY_DEFAULT: u32 = 0x00000002;
+pub const SE_GROUP_ENABLED: u32 = 0x00000004;
+pub const SE_GROUP_OWNER: u32 = 0x00000008;
+
 // Generic access rights mapping
+#[derive(Debug, Clone, Copy)]
 pub struct GenericMapping {
     pub generic_read: u32,
     pub generic_write: u32,
@@ -751,6 +1286,9 @@ pub const STANDARD_RIGHTS_WRITE: u32 = READ_CONTROL;
 pub const STANDARD_RIGHTS_EXECUTE: u32 = READ_CONTROL;
 pub const STANDARD_RIGHTS_ALL: u32 = 0x001F0000;
 
+pub const ACCESS_SYSTEM_SECURITY: u32 = 0x01000000;
+pub const MAXIMUM_ALLOWED: u32 = 0x02000000;
+
 // Generic access rights
 pub const GENERIC_READ: u32 = 0x80000000;
 pub const GENERIC_WRITE: u32 = 0x40000000;
@@ -768,11 +1306,40 @@ pub const FILE_DELETE_CHILD: u32 = 0x00000040;
 pub const FILE_READ_ATTRIBUTES: u32 = 0x00000080;
 pub const FILE_WRITE_ATTRIBUTES: u32 = 0x00000100;
 
+// Directory names for the same bits
+pub const FILE_LIST_DIRECTORY: u32 = FILE_READ_DATA;
+pub const FILE_ADD_FILE: u32 = FILE_WRITE_DATA;
+pub const FILE_ADD_SUBDIRECTORY: u32 = FILE_APPEND_DATA;
+pub const FILE_TRAVERSE: u32 = FILE_EXECUTE;
+
 pub const FILE_ALL_ACCESS: u32 = STANDARD_RIGHTS_REQUIRED | SYNCHRONIZE | 0x1FF;
 pub const FILE_GENERIC_READ: u32 = STANDARD_RIGHTS_READ | FILE_READ_DATA | FILE_READ_ATTRIBUTES | FILE_READ_EA | SYNCHRONIZE;
 pub const FILE_GENERIC_WRITE: u32 = STANDARD_RIGHTS_WRITE | FILE_WRITE_DATA | FILE_WRITE_ATTRIBUTES | FILE_WRITE_EA | FILE_APPEND_DATA | SYNCHRONIZE;
 pub const FILE_GENERIC_EXECUTE: u32 = STANDARD_RIGHTS_EXECUTE | FILE_READ_ATTRIBUTES | FILE_EXECUTE | SYNCHRONIZE;
 
+pub const FILE_GENERIC_MAPPING: GenericMapping = GenericMapping {
+    generic_read: FILE_GENERIC_READ,
+    generic_write: FILE_GENERIC_WRITE,
+    generic_execute: FILE_GENERIC_EXECUTE,
+    generic_all: FILE_ALL_ACCESS,
+};
+
+// Mapping used for kernel objects whose type has no specific rights table
+pub const OBJECT_GENERIC_MAPPING: GenericMapping = GenericMapping {
+    generic_read: STANDARD_RIGHTS_READ | 0x0001,
+    generic_write: STANDARD_RIGHTS_WRITE | 0x0002,
+    generic_execute: STANDARD_RIGHTS_EXECUTE | SYNCHRONIZE | 0x0004,
+    generic_all: STANDARD_RIGHTS_ALL | 0xFFFF,
+};
+
+// SECURITY_INFORMATION flags
+pub const OWNER_SECURITY_INFORMATION: u32 = 0x00000001;
+pub const GROUP_SECURITY_INFORMATION: u32 = 0x00000002;
+pub const DACL_SECURITY_INFORMATION: u32 = 0x00000004;
+pub const SACL_SECURITY_INFORMATION: u32 = 0x00000008;
+pub const UNPROTECTED_DACL_SECURITY_INFORMATION: u32 = 0x20000000;
+pub const PROTECTED_DACL_SECURITY_INFORMATION: u32 = 0x80000000;
+
 // Global security manager
 lazy_static! {
     pub static ref SECURITY_MANAGER: Mutex<SecurityManager> = Mutex::new(SecurityManager::new());
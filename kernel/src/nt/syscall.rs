@@ -145,6 +145,8 @@ impl TryFrom<u32> for SystemCall {
             0x0050 => Ok(SystemCall::NtProtectVirtualMemory),
             0x0028 => Ok(SystemCall::NtMapViewOfSection),
             0x002A => Ok(SystemCall::NtUnmapViewOfSection),
+            0x005A => Ok(SystemCall::NtQuerySystemTime),
+            0x00CE => Ok(SystemCall::NtSetSystemTime),
             _ => Err(()),
         }
     }
@@ -183,6 +185,12 @@ fn handle_syscall(syscall: SystemCall, params: &SyscallParams) -> u64 {
         SystemCall::NtFreeVirtualMemory => {
             handle_nt_free_virtual_memory(params)
         }
+        SystemCall::NtQuerySystemTime => {
+            handle_nt_query_system_time(params)
+        }
+        SystemCall::NtSetSystemTime => {
+            handle_nt_set_system_time(params)
+        }
         _ => {
             serial_println!("Unimplemented syscall: {:?}", syscall);
             NtStatus::NotImplemented as u64
@@ -303,6 +311,40 @@ fn handle_nt_close(params: &SyscallParams) -> u64 {
     om.close_handle(handle) as u64
 }
 
+// NtQuerySystemTime(PLARGE_INTEGER SystemTime)
+fn handle_nt_query_system_time(params: &SyscallParams) -> u64 {
+    let system_time = params.rcx as *mut u64;
+    if system_time.is_null() {
+        return NtStatus::AccessViolation as u64;
+    }
+    unsafe { system_time.write_unaligned(crate::time::filetime_now()); }
+    NtStatus::Success as u64
+}
+
+// NtSetSystemTime(PLARGE_INTEGER SystemTime, PLARGE_INTEGER PreviousTime)
+fn handle_nt_set_system_time(params: &SyscallParams) -> u64 {
+    let system_time = params.rcx as *const u64;
+    let previous_time = params.rdx as *mut u64;
+    if !crate::security::accounts::caller_is_admin() {
+        return NtStatus::PrivilegeNotHeld as u64;
+    }
+    if !previous_time.is_null() {
+        unsafe { previous_time.write_unaligned(crate::time::filetime_now()); }
+    }
+    // A null time only asks for the current one
+    if system_time.is_null() {
+        return NtStatus::Success as u64;
+    }
+    let filetime = unsafe { system_time.read_unaligned() };
+    let Some(ns) = crate::time::filetime_to_unix_ns(filetime) else {
+        return NtStatus::InvalidParameter as u64;
+    };
+    match crate::time::set_realtime_ns(ns) {
+        Ok(()) => NtStatus::Success as u64,
+        Err(_) => NtStatus::Unsuccessful as u64,
+    }
+}
+
 fn handle_nt_allocate_virtual_memory(params: &SyscallParams) -> u64 {
     let process_handle = Handle::from_raw(params.rcx);
     let base_address_ptr = params.rdx as *mut *mut u8;
@@ -0,0 +1,749 @@
+// WDM driver compatibility
+//
+// Loads simple kernel-mode drivers built for Windows. A .sys image is
+// mapped and relocated as the Win32 loader maps a DLL, its imports are
+// bound to the routines in `ntoskrnl`, and DriverEntry is called with a
+// DRIVER_OBJECT laid out as Windows lays it out.
+//
+// Each device a driver creates is also a platform device, `wdm.N`, bound by
+// `PLATFORM_DRIVER`, so it shows in the device tree and /sys. A named device
+// or a \DosDevices link to one can be opened as /dev/wdm/<name>; opening,
+// reading, writing, ioctl and closing each become an IRP sent to the top of
+// the device's stack. Buffered, direct and neither I/O all work: buffered
+// and direct requests go through a kernel copy of the caller's buffer, with
+// an MDL over it for direct I/O, and neither I/O hands the driver the
+// caller's own pointers. A request the driver leaves pending is waited for
+// with interrupts on, as only an interrupt can complete it then.
+//
+// IRPs, device stacks and completion are the I/O manager's, in
+// `drivers::io`, which the drivers built into the kernel use too: a legacy
+// filter can attach to a built-in driver's device by name, and the other
+// way about.
+//
+// There is no PnP or power manager, no DPCs, timers or work items yet, so
+// what runs is a legacy driver that does its work in its dispatch routines.
+
+pub mod ntoskrnl;
+pub mod null;
+pub mod types;
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::VirtAddr;
+
+use self::types::*;
+use crate::driver::{bus, probe, BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::drivers::io;
+use crate::memory::userspace::validate_user_buffer;
+use crate::serial_println;
+use crate::sync::Mutex;
+use crate::syscall::{EACCES, EAGAIN, EBADF, EBUSY, EFAULT, EINVAL, EIO, EMFILE, ENODEV, ENOENT, ENOMEM, ERANGE};
+use crate::win32::loader::{self, ImageMemory, ImportName};
+
+pub const DEVICE_PREFIX: &str = "/dev/wdm/";
+
+// Per-process limit on open devices
+const MAX_HANDLES: usize = 32;
+
+// Clear of /dev/kvm's 3 to 258 and io_uring's from 384
+const FIRST_FD: usize = 320;
+
+/// An ioctl's argument; the request is the I/O control code
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct WdmIoctl {
+    pub input: u64,
+    pub output: u64,
+    pub input_length: u32,
+    pub output_length: u32,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound,
+    BadImage,
+    NoMemory,
+    AlreadyLoaded,
+    /// A module drivers cannot import from
+    UnknownModule(String),
+    /// A routine the named module does not provide
+    UnresolvedImport(String, String),
+    /// DriverEntry's status
+    EntryFailed(NTSTATUS),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotFound => write!(f, "file not found"),
+            LoadError::BadImage => write!(f, "not an x64 driver image"),
+            LoadError::NoMemory => write!(f, "out of memory"),
+            LoadError::AlreadyLoaded => write!(f, "already loaded"),
+            LoadError::UnknownModule(module) => write!(f, "imports from {}, which is not provided", module),
+            LoadError::UnresolvedImport(module, routine) => {
+                write!(f, "imports {}!{}, which is not provided", module, routine)
+            }
+            LoadError::EntryFailed(status) => write!(f, "DriverEntry failed with {:#010x}", *status as u32),
+        }
+    }
+}
+
+struct LoadedDriver {
+    name: String,
+    path: String,
+    object: *mut DriverObject,
+    extension: *mut DriverExtension,
+    // What the driver object's strings point into
+    _driver_name: OwnedUnicode,
+    _service_key: OwnedUnicode,
+    // None for the drivers built into the kernel
+    _image: Option<ImageMemory>,
+}
+
+struct WdmDevice {
+    object: *mut DeviceObject,
+    driver: *mut DriverObject,
+    /// \Device\..., if the device is named
+    name: Option<String>,
+    /// \DosDevices\... links to it
+    links: Vec<String>,
+    extension_size: usize,
+    instance: u32,
+    id: Option<DeviceId>,
+    opens: usize,
+    /// Deleted by its driver while open; freed at the last close
+    deleted: bool,
+}
+
+// The objects belong to the drivers and are only touched under the locks
+// below, or from the drivers' own routines
+unsafe impl Send for LoadedDriver {}
+unsafe impl Send for WdmDevice {}
+
+static DRIVERS: Mutex<Vec<LoadedDriver>> = Mutex::new(Vec::new());
+static DEVICES: Mutex<Vec<WdmDevice>> = Mutex::new(Vec::new());
+static NEXT_INSTANCE: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Copy)]
+struct Handle {
+    device: *mut DeviceObject,
+    file: *mut FileObject,
+}
+
+unsafe impl Send for Handle {}
+
+// Open device by (process, fd)
+static HANDLES: Mutex<BTreeMap<(u32, usize), Handle>> = Mutex::new(BTreeMap::new());
+
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub links: Vec<String>,
+    /// The platform device's name
+    pub platform: String,
+    /// The device this one is attached to, for a filter
+    pub attached_to: Option<String>,
+    pub opens: usize,
+}
+
+pub struct DriverInfo {
+    pub name: String,
+    pub path: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+/// The loaded drivers and their devices
+pub fn list() -> Vec<DriverInfo> {
+    let drivers = DRIVERS.lock();
+    let devices = DEVICES.lock();
+    let describe = |object: *mut DeviceObject| {
+        let device = devices.iter().find(|device| device.object == object);
+        device.map_or(String::from("?"), |device| match &device.name {
+            Some(name) => name.clone(),
+            None => alloc::format!("wdm.{}", device.instance),
+        })
+    };
+    drivers
+        .iter()
+        .map(|driver| DriverInfo {
+            name: driver.name.clone(),
+            path: driver.path.clone(),
+            devices: devices
+                .iter()
+                .filter(|device| device.driver == driver.object && !device.deleted)
+                .map(|device| DeviceInfo {
+                    name: device.name.clone(),
+                    links: device.links.clone(),
+                    platform: alloc::format!("wdm.{}", device.instance),
+                    attached_to: io::lower_device(device.object).map(&describe),
+                    opens: device.opens,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn last_component(name: &str) -> &str {
+    name.rsplit('\\').next().unwrap_or(name)
+}
+
+fn device_layout(extension_size: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(size_of::<DeviceObject>() + extension_size, 16) }
+}
+
+fn allocate<T>() -> *mut T {
+    unsafe { alloc_zeroed(Layout::new::<T>()) }.cast()
+}
+
+unsafe fn free<T>(object: *mut T) {
+    dealloc(object.cast(), Layout::new::<T>());
+}
+
+/// Load a driver from a .sys file, or one built in by its file name;
+/// returns the driver's name
+pub fn load(path: &str) -> Result<String, LoadError> {
+    let file = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let name: String = file.to_ascii_lowercase().trim_end_matches(".sys").into();
+    if DRIVERS.lock().iter().any(|driver| driver.name == name) {
+        return Err(LoadError::AlreadyLoaded);
+    }
+
+    let builtin = io::BUILTIN.iter().find(|(builtin, _)| !path.contains('/') && builtin.eq_ignore_ascii_case(file));
+    let (image, entry) = match builtin {
+        Some(&(_, entry)) => (None, entry),
+        None => {
+            let data = crate::fs::vfs::VFS.lock().read_file(path).map_err(|_| LoadError::NotFound)?;
+            let (mut image, entry_rva) = loader::map_driver_image(&data).map_err(|_| LoadError::BadImage)?;
+            bind_imports(&mut image)?;
+            let entry: DriverInitialize = unsafe { core::mem::transmute(image.base() + entry_rva as u64) };
+            (Some(image), entry)
+        }
+    };
+
+    let object = allocate::<DriverObject>();
+    let extension = allocate::<DriverExtension>();
+    if object.is_null() || extension.is_null() {
+        return Err(LoadError::NoMemory);
+    }
+    let driver_name = OwnedUnicode::new(&alloc::format!("\\Driver\\{}", name));
+    let service_key = OwnedUnicode::new(&name);
+    let mut registry_path =
+        OwnedUnicode::new(&alloc::format!("\\Registry\\Machine\\System\\CurrentControlSet\\Services\\{}", name));
+    unsafe {
+        (*object).kind = IO_TYPE_DRIVER;
+        (*object).size = size_of::<DriverObject>() as i16;
+        (*object).driver_extension = extension;
+        (*object).driver_name = driver_name.string;
+        (*object).driver_init = Some(entry);
+        if let Some(image) = &image {
+            (*object).driver_start = image.base() as *mut c_void;
+            (*object).driver_size = image.bytes().len() as u32;
+        }
+        (*object).major_function = [Some(io::invalid_device_request); IRP_MJ_MAXIMUM_FUNCTION + 1];
+        (*extension).driver_object = object;
+        (*extension).service_key_name = service_key.string;
+    }
+
+    let status = unsafe { entry(object, &mut registry_path.string) };
+    if !nt_success(status) {
+        delete_devices_of(object);
+        unsafe {
+            free(extension);
+            free(object);
+        }
+        return Err(LoadError::EntryFailed(status));
+    }
+    // As the I/O manager does for the devices DriverEntry made
+    for device in DEVICES.lock().iter().filter(|device| device.driver == object) {
+        unsafe { (*device.object).flags &= !DO_DEVICE_INITIALIZING };
+    }
+
+    serial_println!("wdm: loaded {} from {}", name, path);
+    DRIVERS.lock().push(LoadedDriver {
+        name: name.clone(),
+        path: String::from(path),
+        object,
+        extension,
+        _driver_name: driver_name,
+        _service_key: service_key,
+        _image: image,
+    });
+    Ok(name)
+}
+
+fn bind_imports(image: &mut ImageMemory) -> Result<(), LoadError> {
+    let imports = loader::parse_imports(image.bytes()).map_err(|_| LoadError::BadImage)?;
+    for import in imports {
+        let Some(exports) = ntoskrnl::exports(&import.dll_name) else {
+            return Err(LoadError::UnknownModule(import.dll_name));
+        };
+        for (slot, symbol) in import.functions {
+            let routine = match symbol {
+                ImportName::Name(routine) => routine,
+                ImportName::Ordinal(ordinal) => alloc::format!("#{}", ordinal),
+            };
+            let Some(export) = exports.iter().find(|export| export.name == routine) else {
+                return Err(LoadError::UnresolvedImport(import.dll_name, routine));
+            };
+            loader::write_u64(image.bytes_mut(), slot as usize, export.address() as u64).map_err(|_| LoadError::BadImage)?;
+        }
+    }
+    Ok(())
+}
+
+/// Call a driver's unload routine and free it, once none of its devices is
+/// open
+pub fn unload(name: &str) -> Result<(), &'static str> {
+    let mut drivers = DRIVERS.lock();
+    let index = drivers.iter().position(|driver| driver.name.eq_ignore_ascii_case(name)).ok_or("no such driver")?;
+    let object = drivers[index].object;
+    let Some(unload) = (unsafe { (*object).driver_unload }) else {
+        return Err("driver cannot be unloaded");
+    };
+    let devices = DEVICES.lock();
+    let own = devices.iter().filter(|device| device.driver == object && !device.deleted);
+    if own.clone().any(|device| device.opens > 0) {
+        return Err("a device is open");
+    }
+    // A filter above would be left passing IRPs to a freed device
+    let filtered = |device: &WdmDevice| {
+        let upper = unsafe { (*device.object).attached_device };
+        !upper.is_null() && unsafe { (*upper).driver_object } != object
+    };
+    if own.clone().any(filtered) {
+        return Err("another driver is attached to a device");
+    }
+    drop(devices);
+    let driver = drivers.remove(index);
+    drop(drivers);
+
+    unsafe { unload(object) };
+    // Whatever the driver left behind goes with it
+    delete_devices_of(object);
+    unsafe {
+        free(driver.extension);
+        free(object);
+    }
+    serial_println!("wdm: unloaded {}", driver.name);
+    Ok(())
+}
+
+fn delete_devices_of(driver: *mut DriverObject) {
+    let devices: Vec<*mut DeviceObject> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.driver == driver && !device.deleted)
+        .map(|device| device.object)
+        .collect();
+    for device in devices {
+        delete_device(device);
+    }
+}
+
+/// IoCreateDevice
+pub fn create_device(
+    driver: *mut DriverObject,
+    extension_size: u32,
+    name: Option<String>,
+    device_type: u32,
+    characteristics: u32,
+    exclusive: bool,
+) -> Result<*mut DeviceObject, NTSTATUS> {
+    let extension_size = extension_size as usize;
+    if let Some(name) = &name {
+        let devices = DEVICES.lock();
+        if devices.iter().any(|device| device.name.as_deref().is_some_and(|other| other.eq_ignore_ascii_case(name))) {
+            return Err(STATUS_OBJECT_NAME_COLLISION);
+        }
+    }
+    let object = unsafe { alloc_zeroed(device_layout(extension_size)) } as *mut DeviceObject;
+    if object.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    unsafe {
+        (*object).kind = IO_TYPE_DEVICE;
+        (*object).size = (size_of::<DeviceObject>() + extension_size) as u16;
+        (*object).driver_object = driver;
+        (*object).next_device = (*driver).device_object;
+        (*object).flags = DO_DEVICE_INITIALIZING | if exclusive { DO_EXCLUSIVE } else { 0 };
+        (*object).characteristics = characteristics;
+        (*object).device_type = device_type;
+        (*object).stack_size = 1;
+        if extension_size > 0 {
+            (*object).device_extension = object.add(1).cast();
+        }
+        (*driver).device_object = object;
+    }
+
+    let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+    DEVICES.lock().push(WdmDevice {
+        object,
+        driver,
+        name,
+        links: Vec::new(),
+        extension_size,
+        instance,
+        id: None,
+        opens: 0,
+        deleted: false,
+    });
+    // Not under the lock: binding the platform device probes
+    // `PLATFORM_DRIVER`, which takes it
+    match bus::add_platform_device("wdm", instance) {
+        Ok(id) => {
+            if let Some(device) = DEVICES.lock().iter_mut().find(|device| device.object == object) {
+                device.id = Some(id);
+            }
+        }
+        Err(error) => serial_println!("wdm: cannot add wdm.{}: {}", instance, error),
+    }
+    Ok(object)
+}
+
+/// IoDeleteDevice
+pub fn delete_device(object: *mut DeviceObject) {
+    let mut devices = DEVICES.lock();
+    let Some(index) = devices.iter().position(|device| device.object == object && !device.deleted) else {
+        return;
+    };
+    let device = &mut devices[index];
+    let driver = device.driver;
+    let id = device.id.take();
+    let open = device.opens > 0;
+    device.deleted = true;
+    device.links.clear();
+    let device = (!open).then(|| devices.remove(index));
+    drop(devices);
+
+    unsafe { io::remove_from_stack(object) };
+
+    // Off the driver's list
+    unsafe {
+        let mut link = ptr::addr_of_mut!((*driver).device_object);
+        while !(*link).is_null() && *link != object {
+            link = ptr::addr_of_mut!((**link).next_device);
+        }
+        if *link == object {
+            *link = (*object).next_device;
+        }
+    }
+    if let Some(id) = id {
+        probe::unregister_device(id);
+    }
+    if let Some(device) = device {
+        unsafe { dealloc(object.cast(), device_layout(device.extension_size)) };
+    }
+}
+
+/// IoCreateSymbolicLink; only links to devices are kept
+pub fn create_link(link: String, target: &str) -> NTSTATUS {
+    let mut devices = DEVICES.lock();
+    if devices.iter().flat_map(|device| &device.links).any(|other| other.eq_ignore_ascii_case(&link)) {
+        return STATUS_OBJECT_NAME_COLLISION;
+    }
+    let named = |device: &&mut WdmDevice| device.name.as_deref().is_some_and(|name| name.eq_ignore_ascii_case(target));
+    match devices.iter_mut().filter(|device| !device.deleted).find(named) {
+        Some(device) => {
+            device.links.push(link);
+            STATUS_SUCCESS
+        }
+        None => STATUS_OBJECT_NAME_NOT_FOUND,
+    }
+}
+
+/// IoDeleteSymbolicLink
+pub fn delete_link(link: &str) -> NTSTATUS {
+    for device in DEVICES.lock().iter_mut() {
+        if let Some(index) = device.links.iter().position(|other| other.eq_ignore_ascii_case(link)) {
+            device.links.remove(index);
+            return STATUS_SUCCESS;
+        }
+    }
+    STATUS_OBJECT_NAME_NOT_FOUND
+}
+
+/// A device by its \Device name or a link to it
+pub fn find_device(name: &str) -> Option<*mut DeviceObject> {
+    let devices = DEVICES.lock();
+    let named = |device: &&WdmDevice| {
+        device.links.iter().chain(&device.name).any(|other| other.eq_ignore_ascii_case(name))
+    };
+    devices.iter().filter(|device| !device.deleted).find(named).map(|device| device.object)
+}
+
+fn errno(status: NTSTATUS) -> usize {
+    match status {
+        STATUS_INVALID_PARAMETER | STATUS_INVALID_DEVICE_REQUEST | STATUS_NOT_SUPPORTED => EINVAL,
+        STATUS_BUFFER_TOO_SMALL => ERANGE,
+        STATUS_ACCESS_DENIED => EACCES,
+        STATUS_INSUFFICIENT_RESOURCES => ENOMEM,
+        STATUS_DEVICE_BUSY => EBUSY,
+        STATUS_DEVICE_NOT_READY => EAGAIN,
+        STATUS_OBJECT_NAME_NOT_FOUND => ENOENT,
+        STATUS_NO_SUCH_DEVICE => ENODEV,
+        _ => EIO,
+    }
+}
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+fn handle(fd: usize) -> Result<Handle, usize> {
+    HANDLES.lock().get(&(current_pid(), fd)).copied().ok_or(EBADF)
+}
+
+/// Whether `fd` is a WDM device the caller has open
+pub fn owns(fd: usize) -> bool {
+    HANDLES.lock().contains_key(&(current_pid(), fd))
+}
+
+/// Open `/dev/wdm/<name>`, where the name is a device's or a \DosDevices
+/// link's last component; None if `path` is not under /dev/wdm
+pub fn open(path: &str) -> Option<Result<usize, usize>> {
+    path.strip_prefix(DEVICE_PREFIX).map(open_name)
+}
+
+fn open_name(name: &str) -> Result<usize, usize> {
+    let pid = current_pid();
+    let used: Vec<usize> = HANDLES.lock().range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_HANDLES {
+        return Err(EMFILE);
+    }
+    let device = {
+        let mut devices = DEVICES.lock();
+        let matches = |device: &&mut WdmDevice| {
+            device.links.iter().chain(&device.name).any(|full| last_component(full).eq_ignore_ascii_case(name))
+        };
+        let device = devices.iter_mut().filter(|device| !device.deleted).find(matches).ok_or(ENOENT)?;
+        let flags = unsafe { (*device.object).flags };
+        if flags & DO_DEVICE_INITIALIZING != 0 {
+            return Err(ENODEV);
+        }
+        if flags & DO_EXCLUSIVE != 0 && device.opens > 0 {
+            return Err(EBUSY);
+        }
+        device.opens += 1;
+        device.object
+    };
+
+    let file = allocate::<FileObject>();
+    if file.is_null() {
+        closed(device);
+        return Err(ENOMEM);
+    }
+    unsafe {
+        (*file).kind = IO_TYPE_FILE;
+        (*file).size = size_of::<FileObject>() as i16;
+        (*file).device_object = device;
+        let status = io::send(device, file, IRP_MJ_CREATE, USER_MODE, |_, _| {}).status;
+        if !nt_success(status) {
+            free(file);
+            closed(device);
+            return Err(errno(status));
+        }
+    }
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    HANDLES.lock().insert((pid, fd), Handle { device, file });
+    Ok(fd)
+}
+
+// One open of `device` fewer, freeing it if its driver deleted it
+fn closed(object: *mut DeviceObject) {
+    let mut devices = DEVICES.lock();
+    let Some(index) = devices.iter().position(|device| device.object == object) else {
+        return;
+    };
+    devices[index].opens -= 1;
+    if devices[index].deleted && devices[index].opens == 0 {
+        let device = devices.remove(index);
+        unsafe { dealloc(object.cast(), device_layout(device.extension_size)) };
+    }
+}
+
+fn close_handle(handle: Handle) {
+    unsafe {
+        io::send(handle.device, handle.file, IRP_MJ_CLEANUP, USER_MODE, |_, _| {});
+        io::send(handle.device, handle.file, IRP_MJ_CLOSE, USER_MODE, |_, _| {});
+        free(handle.file);
+    }
+    closed(handle.device);
+}
+
+/// Close a handle; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let Some(handle) = HANDLES.lock().remove(&(current_pid(), fd)) else {
+        return false;
+    };
+    close_handle(handle);
+    true
+}
+
+/// Close every device an exiting process has open
+pub fn release_process(pid: u32) {
+    let handles: Vec<Handle> = {
+        let mut handles = HANDLES.lock();
+        let fds: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+        fds.into_iter().filter_map(|fd| handles.remove(&(pid, fd))).collect()
+    };
+    for handle in handles {
+        close_handle(handle);
+    }
+}
+
+// A read or write at the file's current offset
+fn transfer(fd: usize, major: u8, buffer: *mut u8, length: usize) -> Result<usize, usize> {
+    let handle = handle(fd)?;
+    let length = length.min(u32::MAX as usize);
+    let flags = unsafe { (*io::attached_top(handle.device)).flags };
+    let copied = flags & (DO_BUFFERED_IO | DO_DIRECT_IO) != 0;
+    let mut bounce = vec![0u8; if copied { length } else { 0 }];
+    if copied && major == IRP_MJ_WRITE {
+        bounce.copy_from_slice(unsafe { core::slice::from_raw_parts(buffer, length) });
+    }
+    let mdl = match flags & DO_DIRECT_IO {
+        0 => ptr::null_mut(),
+        _ => io::allocate_mdl(bounce.as_mut_ptr(), length),
+    };
+    if flags & DO_DIRECT_IO != 0 && mdl.is_null() {
+        return Err(ENOMEM);
+    }
+    let iosb = unsafe {
+        let offset = (*handle.file).current_byte_offset;
+        io::send(handle.device, handle.file, major, USER_MODE, |irp, stack| {
+            stack.set_read_write(length as u32, offset);
+            (*irp).user_buffer = buffer.cast();
+            if flags & DO_BUFFERED_IO != 0 {
+                (*irp).system_buffer = bounce.as_mut_ptr().cast();
+            } else {
+                (*irp).mdl_address = mdl;
+            }
+        })
+    };
+    match iosb.status {
+        STATUS_END_OF_FILE => Ok(0),
+        status if nt_success(status) => {
+            let count = (iosb.information as usize).min(length);
+            if copied && major == IRP_MJ_READ {
+                unsafe { ptr::copy_nonoverlapping(bounce.as_ptr(), buffer, count) };
+            }
+            unsafe { (*handle.file).current_byte_offset += count as i64 };
+            Ok(count)
+        }
+        status => Err(errno(status)),
+    }
+}
+
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, usize> {
+    transfer(fd, IRP_MJ_READ, buffer.as_mut_ptr(), buffer.len())
+}
+
+pub fn write(fd: usize, bytes: &[u8]) -> Result<usize, usize> {
+    transfer(fd, IRP_MJ_WRITE, bytes.as_ptr() as *mut u8, bytes.len())
+}
+
+/// Send I/O control `request` with the buffers `arg`, a `WdmIoctl`,
+/// describes; returns the bytes the driver returned
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, usize> {
+    let handle = handle(fd)?;
+    let code = u32::try_from(request).map_err(|_| EINVAL)?;
+    let addr = VirtAddr::try_new(arg as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, size_of::<WdmIoctl>()) {
+        return Err(EFAULT);
+    }
+    let args = unsafe { (arg as *const WdmIoctl).read_unaligned() };
+    for (address, length) in [(args.input, args.input_length), (args.output, args.output_length)] {
+        if length > 0 {
+            let addr = VirtAddr::try_new(address).map_err(|_| EFAULT)?;
+            if !validate_user_buffer(addr, length as usize) {
+                return Err(EFAULT);
+            }
+        }
+    }
+    let (input, output) = (args.input as *mut u8, args.output as *mut u8);
+    let (input_length, output_length) = (args.input_length as usize, args.output_length as usize);
+    let method = code & 3;
+
+    // The system buffer: the input, with room for the output when buffered
+    let system_length = match method {
+        METHOD_BUFFERED => input_length.max(output_length),
+        METHOD_NEITHER => 0,
+        _ => input_length,
+    };
+    let mut system = vec![0u8; system_length];
+    if method != METHOD_NEITHER && input_length > 0 {
+        system[..input_length].copy_from_slice(unsafe { core::slice::from_raw_parts(input, input_length) });
+    }
+    // Direct I/O's output, read by the driver for METHOD_IN_DIRECT
+    let mut direct = vec![0u8; if matches!(method, METHOD_IN_DIRECT | METHOD_OUT_DIRECT) { output_length } else { 0 }];
+    if method == METHOD_IN_DIRECT && output_length > 0 {
+        direct.copy_from_slice(unsafe { core::slice::from_raw_parts(output, output_length) });
+    }
+    let mdl = match direct.is_empty() {
+        true => ptr::null_mut(),
+        false => io::allocate_mdl(direct.as_mut_ptr(), output_length),
+    };
+    if !direct.is_empty() && mdl.is_null() {
+        return Err(ENOMEM);
+    }
+
+    let iosb = unsafe {
+        io::send(handle.device, handle.file, IRP_MJ_DEVICE_CONTROL, USER_MODE, |irp, stack| {
+            let type3 = if method == METHOD_NEITHER { input.cast() } else { ptr::null_mut() };
+            stack.set_device_control(code, type3, input_length as u32, output_length as u32);
+            (*irp).user_buffer = output.cast();
+            if !system.is_empty() {
+                (*irp).system_buffer = system.as_mut_ptr().cast();
+            }
+            (*irp).mdl_address = mdl;
+        })
+    };
+    if !nt_success(iosb.status) {
+        return Err(errno(iosb.status));
+    }
+    let count = (iosb.information as usize).min(output_length);
+    let returned = match method {
+        METHOD_BUFFERED => &system[..count],
+        METHOD_OUT_DIRECT => &direct[..count],
+        _ => &[][..],
+    };
+    unsafe { ptr::copy_nonoverlapping(returned.as_ptr(), output, returned.len()) };
+    Ok(count)
+}
+
+/// Binds the platform devices that stand for WDM devices
+pub static PLATFORM_DRIVER: WdmPlatformDriver = WdmPlatformDriver;
+
+pub struct WdmPlatformDriver;
+
+impl Driver for WdmPlatformDriver {
+    fn name(&self) -> &'static str {
+        "wdm"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Platform
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[Match::Platform("wdm")]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        match device.ident {
+            Ident::Platform { instance, .. }
+                if DEVICES.lock().iter().any(|wdm| wdm.instance == instance && !wdm.deleted) =>
+            {
+                Ok(())
+            }
+            _ => Err(ProbeError::NoDevice),
+        }
+    }
+}
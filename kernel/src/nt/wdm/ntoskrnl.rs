@@ -0,0 +1,560 @@
+// The ntoskrnl.exe and hal.dll routines WDM drivers import
+//
+// A .sys image is built for the Microsoft x64 calling convention, so these
+// are `extern "win64"`, where the Win32 DLLs' `extern "C"` is System V in
+// this kernel. Imports are bound by name against the tables at the end; a
+// driver importing anything else does not load.
+//
+// IRQL is kept per CPU rather than in CR8. Raising it to DISPATCH_LEVEL, as
+// taking a spin lock does, turns interrupts off until it is lowered again.
+
+#![allow(non_snake_case)]
+
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use core::ffi::c_void;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::types::*;
+use crate::drivers::io;
+use crate::serial_println;
+use crate::smp::MAX_CPUS;
+use crate::sync::Mutex;
+
+// Pool blocks by address, with their size and tag
+static POOL: Mutex<BTreeMap<u64, (usize, u32)>> = Mutex::new(BTreeMap::new());
+
+const POOL_ALIGN: usize = 16;
+
+static IRQL: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(PASSIVE_LEVEL) }; MAX_CPUS];
+// Whether interrupts were on when each CPU left PASSIVE_LEVEL
+static INTERRUPTS_WERE_ON: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+fn pool_allocate(size: usize, tag: u32) -> *mut c_void {
+    let size = size.max(1);
+    let Ok(layout) = Layout::from_size_align(size, POOL_ALIGN) else {
+        return ptr::null_mut();
+    };
+    let block = unsafe { alloc_zeroed(layout) };
+    if !block.is_null() {
+        POOL.lock().insert(block as u64, (size, tag));
+    }
+    block.cast()
+}
+
+fn pool_free(block: *mut c_void) {
+    let Some((size, _)) = POOL.lock().remove(&(block as u64)) else {
+        serial_println!("wdm: freeing {:p}, which is not pool", block);
+        return;
+    };
+    unsafe { dealloc(block.cast(), Layout::from_size_align_unchecked(size, POOL_ALIGN)) };
+}
+
+/// Pool in use: blocks and bytes
+pub fn pool_usage() -> (usize, usize) {
+    let pool = POOL.lock();
+    (pool.len(), pool.values().map(|&(size, _)| size).sum())
+}
+
+fn cpu() -> usize {
+    crate::smp::current_cpu_id() as usize % MAX_CPUS
+}
+
+fn raise_to_dispatch() -> KIRQL {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let cpu = cpu();
+    let old = IRQL[cpu].swap(DISPATCH_LEVEL, Ordering::Relaxed);
+    if old < DISPATCH_LEVEL {
+        INTERRUPTS_WERE_ON[cpu].store(enabled, Ordering::Relaxed);
+    }
+    old
+}
+
+fn lower_irql(irql: KIRQL) {
+    let cpu = cpu();
+    IRQL[cpu].store(irql, Ordering::Relaxed);
+    if irql < DISPATCH_LEVEL && INTERRUPTS_WERE_ON[cpu].load(Ordering::Relaxed) {
+        interrupts::enable();
+    }
+}
+
+unsafe fn spin_lock(lock: *mut KSPIN_LOCK) {
+    let lock = AtomicU64::from_ptr(lock);
+    while lock.compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn spin_unlock(lock: *mut KSPIN_LOCK) {
+    AtomicU64::from_ptr(lock).store(0, Ordering::Release);
+}
+
+// Ex
+
+pub unsafe extern "win64" fn ExAllocatePool(_pool_type: u32, size: usize) -> *mut c_void {
+    pool_allocate(size, u32::from_le_bytes(*b"None"))
+}
+
+pub unsafe extern "win64" fn ExAllocatePoolWithTag(_pool_type: u32, size: usize, tag: u32) -> *mut c_void {
+    pool_allocate(size, tag)
+}
+
+pub unsafe extern "win64" fn ExAllocatePool2(_flags: u64, size: usize, tag: u32) -> *mut c_void {
+    pool_allocate(size, tag)
+}
+
+pub unsafe extern "win64" fn ExFreePool(block: *mut c_void) {
+    pool_free(block);
+}
+
+pub unsafe extern "win64" fn ExFreePoolWithTag(block: *mut c_void, _tag: u32) {
+    pool_free(block);
+}
+
+// Io
+
+pub unsafe extern "win64" fn IoCreateDevice(
+    driver: *mut DriverObject,
+    extension_size: u32,
+    name: *mut UnicodeString,
+    device_type: u32,
+    characteristics: u32,
+    exclusive: u8,
+    device: *mut *mut DeviceObject,
+) -> NTSTATUS {
+    let name = name.as_ref().map(|name| name.text());
+    match super::create_device(driver, extension_size, name, device_type, characteristics, exclusive != 0) {
+        Ok(created) => {
+            *device = created;
+            STATUS_SUCCESS
+        }
+        Err(status) => status,
+    }
+}
+
+pub unsafe extern "win64" fn IoDeleteDevice(device: *mut DeviceObject) {
+    super::delete_device(device);
+}
+
+pub unsafe extern "win64" fn IoCreateSymbolicLink(link: *mut UnicodeString, target: *mut UnicodeString) -> NTSTATUS {
+    match (link.as_ref(), target.as_ref()) {
+        (Some(link), Some(target)) => super::create_link(link.text(), &target.text()),
+        _ => STATUS_INVALID_PARAMETER,
+    }
+}
+
+pub unsafe extern "win64" fn IoDeleteSymbolicLink(link: *mut UnicodeString) -> NTSTATUS {
+    match link.as_ref() {
+        Some(link) => super::delete_link(&link.text()),
+        None => STATUS_INVALID_PARAMETER,
+    }
+}
+
+pub unsafe extern "win64" fn IoAttachDevice(
+    source: *mut DeviceObject,
+    target: *mut UnicodeString,
+    attached_to: *mut *mut DeviceObject,
+) -> NTSTATUS {
+    let Some(target) = target.as_ref().and_then(|name| super::find_device(&name.text())) else {
+        return STATUS_OBJECT_NAME_NOT_FOUND;
+    };
+    IoAttachDeviceToDeviceStackSafe(source, target, attached_to)
+}
+
+pub unsafe extern "win64" fn IoAttachDeviceToDeviceStack(
+    source: *mut DeviceObject,
+    target: *mut DeviceObject,
+) -> *mut DeviceObject {
+    io::attach(source, target).unwrap_or(ptr::null_mut())
+}
+
+pub unsafe extern "win64" fn IoAttachDeviceToDeviceStackSafe(
+    source: *mut DeviceObject,
+    target: *mut DeviceObject,
+    attached_to: *mut *mut DeviceObject,
+) -> NTSTATUS {
+    match io::attach(source, target) {
+        Some(lower) => {
+            *attached_to = lower;
+            STATUS_SUCCESS
+        }
+        None => STATUS_INVALID_PARAMETER,
+    }
+}
+
+pub unsafe extern "win64" fn IoDetachDevice(target: *mut DeviceObject) {
+    io::detach(target);
+}
+
+pub unsafe extern "win64" fn IoGetAttachedDevice(device: *mut DeviceObject) -> *mut DeviceObject {
+    io::attached_top(device)
+}
+
+pub unsafe extern "win64" fn IoGetAttachedDeviceReference(device: *mut DeviceObject) -> *mut DeviceObject {
+    io::attached_top(device)
+}
+
+pub unsafe extern "win64" fn IoGetLowerDeviceObject(device: *mut DeviceObject) -> *mut DeviceObject {
+    io::lower_device(device).unwrap_or(ptr::null_mut())
+}
+
+pub unsafe extern "win64" fn IoAllocateIrp(stack_size: i8, _charge_quota: u8) -> *mut Irp {
+    io::allocate_irp(stack_size)
+}
+
+pub unsafe extern "win64" fn IoFreeIrp(irp: *mut Irp) {
+    io::free_irp(irp);
+}
+
+pub unsafe extern "win64" fn IofCallDriver(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    io::call_driver(device, irp)
+}
+
+pub unsafe extern "win64" fn IofCompleteRequest(irp: *mut Irp, _priority_boost: i8) {
+    io::complete_request(irp);
+}
+
+// Ke
+
+pub unsafe extern "win64" fn KeGetCurrentIrql() -> KIRQL {
+    IRQL[cpu()].load(Ordering::Relaxed)
+}
+
+pub unsafe extern "win64" fn KeInitializeSpinLock(lock: *mut KSPIN_LOCK) {
+    *lock = 0;
+}
+
+pub unsafe extern "win64" fn KeAcquireSpinLockRaiseToDpc(lock: *mut KSPIN_LOCK) -> KIRQL {
+    let old = raise_to_dispatch();
+    spin_lock(lock);
+    old
+}
+
+pub unsafe extern "win64" fn KeReleaseSpinLock(lock: *mut KSPIN_LOCK, irql: KIRQL) {
+    spin_unlock(lock);
+    lower_irql(irql);
+}
+
+pub unsafe extern "win64" fn KeAcquireSpinLockAtDpcLevel(lock: *mut KSPIN_LOCK) {
+    spin_lock(lock);
+}
+
+pub unsafe extern "win64" fn KeReleaseSpinLockFromDpcLevel(lock: *mut KSPIN_LOCK) {
+    spin_unlock(lock);
+}
+
+pub unsafe extern "win64" fn KeStallExecutionProcessor(microseconds: u32) {
+    let frequency = crate::timer::get_tsc_frequency().max(1_000_000);
+    let end = crate::timer::rdtsc() + microseconds as u64 * (frequency / 1_000_000);
+    while crate::timer::rdtsc() < end {
+        core::hint::spin_loop();
+    }
+}
+
+pub unsafe extern "win64" fn KeBugCheckEx(code: u32, first: u64, second: u64, third: u64, fourth: u64) -> ! {
+    panic!("wdm: bug check {:#x} ({:#x}, {:#x}, {:#x}, {:#x})", code, first, second, third, fourth);
+}
+
+// Ob: objects are not reference counted, but live until whoever made them
+// deletes them, so these keep the count at one
+
+pub unsafe extern "win64" fn ObfReferenceObject(_object: *mut c_void) -> isize {
+    1
+}
+
+pub unsafe extern "win64" fn ObfDereferenceObject(_object: *mut c_void) -> isize {
+    1
+}
+
+// Mm
+
+pub unsafe extern "win64" fn MmGetSystemRoutineAddress(name: *mut UnicodeString) -> *mut c_void {
+    let Some(name) = name.as_ref().map(|name| name.text()) else {
+        return ptr::null_mut();
+    };
+    ["ntoskrnl.exe", "hal.dll"]
+        .iter()
+        .filter_map(|module| exports(module))
+        .flat_map(|table| table.iter())
+        .find(|export| export.name == name)
+        .map_or(ptr::null_mut(), |export| export.address() as *mut c_void)
+}
+
+/// Physical memory is identity mapped, device memory included
+pub unsafe extern "win64" fn MmMapIoSpace(physical: u64, _size: usize, _cache_type: u32) -> *mut c_void {
+    physical as *mut c_void
+}
+
+pub unsafe extern "win64" fn MmUnmapIoSpace(_base: *mut c_void, _size: usize) {}
+
+/// Every MDL this layer builds is over mapped kernel memory already
+pub unsafe extern "win64" fn MmMapLockedPagesSpecifyCache(
+    mdl: *mut Mdl,
+    _access_mode: i8,
+    _cache_type: u32,
+    _requested_address: *mut c_void,
+    _bug_check_on_failure: u32,
+    _priority: u32,
+) -> *mut c_void {
+    (*mdl).mapped_system_va
+}
+
+// Rtl and the C library
+
+pub unsafe extern "win64" fn RtlInitUnicodeString(string: *mut UnicodeString, source: *const u16) {
+    let length = match source.is_null() {
+        true => 0,
+        false => (0..).take_while(|&index| *source.add(index) != 0).count(),
+    };
+    *string = UnicodeString {
+        length: (length * 2) as u16,
+        maximum_length: if source.is_null() { 0 } else { (length * 2 + 2) as u16 },
+        buffer: source as *mut u16,
+    };
+}
+
+pub unsafe extern "win64" fn memcpy(destination: *mut u8, source: *const u8, count: usize) -> *mut u8 {
+    ptr::copy_nonoverlapping(source, destination, count);
+    destination
+}
+
+pub unsafe extern "win64" fn memmove(destination: *mut u8, source: *const u8, count: usize) -> *mut u8 {
+    ptr::copy(source, destination, count);
+    destination
+}
+
+pub unsafe extern "win64" fn memset(destination: *mut u8, value: i32, count: usize) -> *mut u8 {
+    ptr::write_bytes(destination, value as u8, count);
+    destination
+}
+
+// Debug output
+
+/// The arguments after the format are read as they would be passed: the
+/// first three in registers, the rest from the caller's stack
+pub unsafe extern "win64" fn DbgPrint(
+    format: *const u8,
+    a: u64,
+    b: u64,
+    c: u64,
+    d: u64,
+    e: u64,
+    f: u64,
+    g: u64,
+) -> NTSTATUS {
+    debug_print(format, &[a, b, c, d, e, f, g]);
+    STATUS_SUCCESS
+}
+
+pub unsafe extern "win64" fn DbgPrintEx(
+    _component: u32,
+    _level: u32,
+    format: *const u8,
+    a: u64,
+    b: u64,
+    c: u64,
+    d: u64,
+    e: u64,
+) -> NTSTATUS {
+    debug_print(format, &[a, b, c, d, e]);
+    STATUS_SUCCESS
+}
+
+unsafe fn c_string(text: *const u8) -> String {
+    if text.is_null() {
+        return String::from("(null)");
+    }
+    let length = (0..).take_while(|&index| *text.add(index) != 0).count();
+    String::from_utf8_lossy(core::slice::from_raw_parts(text, length)).into_owned()
+}
+
+unsafe fn wide_string(text: *const u16) -> String {
+    if text.is_null() {
+        return String::from("(null)");
+    }
+    let length = (0..).take_while(|&index| *text.add(index) != 0).count();
+    String::from_utf16_lossy(core::slice::from_raw_parts(text, length))
+}
+
+// printf as the kernel's DbgPrint takes it, widths and all but for
+// floating point
+unsafe fn debug_print(format: *const u8, args: &[u64]) {
+    let format = c_string(format);
+    let mut args = args.iter().copied();
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            out.push(ch);
+            continue;
+        }
+        let left = chars.next_if_eq(&'-').is_some();
+        let zero = chars.next_if_eq(&'0').is_some();
+        let mut width = 0;
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            width = width * 10 + digit.to_digit(10).unwrap_or(0) as usize;
+        }
+        // Length modifiers. A long is 32 bits on Windows, so only ll, I64,
+        // I and z take 64; l and w also mark a string wide
+        let mut modifiers = String::new();
+        while let Some(modifier) = chars.next_if(|ch| matches!(ch, 'l' | 'h' | 'z' | 'I' | '6' | '4' | 'w')) {
+            modifiers.push(modifier);
+        }
+        let wide = modifiers.contains(['l', 'w']);
+        let long = modifiers.contains("ll") || modifiers.contains(['I', 'z']);
+        let mut next = || args.next().unwrap_or(0);
+        let text = match chars.next() {
+            Some('%') => String::from("%"),
+            Some('d' | 'i') if long => format!("{}", next() as i64),
+            Some('d' | 'i') => format!("{}", next() as i32),
+            Some('u') if long => format!("{}", next()),
+            Some('u') => format!("{}", next() as u32),
+            Some('x') if long => format!("{:x}", next()),
+            Some('x') => format!("{:x}", next() as u32),
+            Some('X') if long => format!("{:X}", next()),
+            Some('X') => format!("{:X}", next() as u32),
+            Some('p') => format!("{:016X}", next()),
+            Some('c') => String::from(char::from(next() as u8)),
+            Some('s') if wide => wide_string(next() as *const u16),
+            Some('s') => c_string(next() as *const u8),
+            Some('S') => wide_string(next() as *const u16),
+            Some('Z') => match (next() as *const UnicodeString).as_ref() {
+                Some(string) => string.text(),
+                None => String::from("(null)"),
+            },
+            Some(other) => format!("%{}", other),
+            None => String::from("%"),
+        };
+        let pad = width.saturating_sub(text.chars().count());
+        match (left, zero) {
+            (true, _) => {
+                out.push_str(&text);
+                out.extend(core::iter::repeat(' ').take(pad));
+            }
+            (false, true) => {
+                out.extend(core::iter::repeat('0').take(pad));
+                out.push_str(&text);
+            }
+            (false, false) => {
+                out.extend(core::iter::repeat(' ').take(pad));
+                out.push_str(&text);
+            }
+        }
+    }
+    serial_println!("wdm: {}", out.trim_end_matches(['\r', '\n']));
+}
+
+// hal.dll
+
+pub unsafe extern "win64" fn READ_PORT_UCHAR(port: *mut u8) -> u8 {
+    Port::new(port as u16).read()
+}
+
+pub unsafe extern "win64" fn READ_PORT_USHORT(port: *mut u16) -> u16 {
+    Port::new(port as u16).read()
+}
+
+pub unsafe extern "win64" fn READ_PORT_ULONG(port: *mut u32) -> u32 {
+    Port::new(port as u16).read()
+}
+
+pub unsafe extern "win64" fn WRITE_PORT_UCHAR(port: *mut u8, value: u8) {
+    Port::new(port as u16).write(value);
+}
+
+pub unsafe extern "win64" fn WRITE_PORT_USHORT(port: *mut u16, value: u16) {
+    Port::new(port as u16).write(value);
+}
+
+pub unsafe extern "win64" fn WRITE_PORT_ULONG(port: *mut u32, value: u32) {
+    Port::new(port as u16).write(value);
+}
+
+/// A routine exported to drivers. A static cannot turn a function pointer
+/// into an integer, so the tables keep the pointer.
+#[derive(Clone, Copy)]
+pub struct Export {
+    pub name: &'static str,
+    routine: *const (),
+}
+
+// Only ever read, and the routines it points at live forever
+unsafe impl Sync for Export {}
+
+impl Export {
+    pub fn address(&self) -> usize {
+        self.routine as usize
+    }
+}
+
+macro_rules! exports {
+    ($($name:ident),* $(,)?) => {{
+        static EXPORTS: &[Export] = &[$(Export { name: stringify!($name), routine: $name as *const () }),*];
+        EXPORTS
+    }};
+}
+
+/// The routines a module exports to drivers, by name; None for a module
+/// drivers cannot import from
+pub fn exports(module: &str) -> Option<&'static [Export]> {
+    let table: &'static [Export] = match module.to_ascii_lowercase().as_str() {
+        "ntoskrnl.exe" => exports! {
+            DbgPrint,
+            DbgPrintEx,
+            ExAllocatePool,
+            ExAllocatePool2,
+            ExAllocatePoolWithTag,
+            ExFreePool,
+            ExFreePoolWithTag,
+            IoAllocateIrp,
+            IoAttachDevice,
+            IoAttachDeviceToDeviceStack,
+            IoAttachDeviceToDeviceStackSafe,
+            IoCreateDevice,
+            IoCreateSymbolicLink,
+            IoDeleteDevice,
+            IoDeleteSymbolicLink,
+            IoDetachDevice,
+            IoFreeIrp,
+            IoGetAttachedDevice,
+            IoGetAttachedDeviceReference,
+            IoGetLowerDeviceObject,
+            IofCallDriver,
+            IofCompleteRequest,
+            KeAcquireSpinLockAtDpcLevel,
+            KeAcquireSpinLockRaiseToDpc,
+            KeBugCheckEx,
+            KeGetCurrentIrql,
+            KeInitializeSpinLock,
+            KeReleaseSpinLock,
+            KeReleaseSpinLockFromDpcLevel,
+            MmGetSystemRoutineAddress,
+            MmMapIoSpace,
+            MmMapLockedPagesSpecifyCache,
+            MmUnmapIoSpace,
+            ObfDereferenceObject,
+            ObfReferenceObject,
+            RtlInitUnicodeString,
+            memcpy,
+            memmove,
+            memset,
+        },
+        "hal.dll" => exports! {
+            KeStallExecutionProcessor,
+            READ_PORT_UCHAR,
+            READ_PORT_ULONG,
+            READ_PORT_USHORT,
+            WRITE_PORT_UCHAR,
+            WRITE_PORT_ULONG,
+            WRITE_PORT_USHORT,
+        },
+        _ => return None,
+    };
+    Some(table)
+}
@@ -0,0 +1,134 @@
+// The null device, written as a WDM driver
+//
+// Built in so the WDM layer can be tried without a Windows driver on disk:
+// `wdm load null.sys` runs this DriverEntry as it would a file's, and the
+// driver goes through the same ntoskrnl routines a .sys image imports.
+// Reads find end of file and writes are thrown away, as on \Device\Null;
+// IOCTL_NULL_GET_STATS counts what was asked of it, under a spin lock.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+
+use super::ntoskrnl::*;
+use super::types::*;
+
+const FILE_DEVICE_NULL: u32 = 0x15;
+const FILE_ANY_ACCESS: u32 = 0;
+
+pub const IOCTL_NULL_GET_STATS: u32 = ctl_code(FILE_DEVICE_NULL, 0x800, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
+/// IOCTL_NULL_GET_STATS's output
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullStats {
+    pub opens: u64,
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+}
+
+#[repr(C)]
+struct Extension {
+    lock: KSPIN_LOCK,
+    stats: NullStats,
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain([0]).collect()
+}
+
+unsafe fn extension(device: *mut DeviceObject) -> *mut Extension {
+    (*device).device_extension.cast()
+}
+
+unsafe fn complete(irp: *mut Irp, status: NTSTATUS, information: u64) -> NTSTATUS {
+    (*irp).io_status = IoStatusBlock { status, information };
+    IofCompleteRequest(irp, 0);
+    status
+}
+
+unsafe fn count(device: *mut DeviceObject, update: impl FnOnce(&mut NullStats)) {
+    let extension = extension(device);
+    let irql = KeAcquireSpinLockRaiseToDpc(&mut (*extension).lock);
+    update(&mut (*extension).stats);
+    KeReleaseSpinLock(&mut (*extension).lock, irql);
+}
+
+pub unsafe extern "win64" fn driver_entry(driver: *mut DriverObject, _registry_path: *mut UnicodeString) -> NTSTATUS {
+    let (device_name, link_name) = (wide("\\Device\\Null"), wide("\\DosDevices\\Null"));
+    let mut name = UnicodeString { length: 0, maximum_length: 0, buffer: ptr::null_mut() };
+    let mut link = name;
+    RtlInitUnicodeString(&mut name, device_name.as_ptr());
+    RtlInitUnicodeString(&mut link, link_name.as_ptr());
+
+    let mut device = ptr::null_mut();
+    let extension_size = size_of::<Extension>() as u32;
+    let status = IoCreateDevice(driver, extension_size, &mut name, FILE_DEVICE_NULL, 0, 0, &mut device);
+    if !nt_success(status) {
+        return status;
+    }
+    let status = IoCreateSymbolicLink(&mut link, &mut name);
+    if !nt_success(status) {
+        IoDeleteDevice(device);
+        return status;
+    }
+    (*device).flags |= DO_BUFFERED_IO;
+    KeInitializeSpinLock(&mut (*extension(device)).lock);
+
+    for major in [IRP_MJ_CREATE, IRP_MJ_CLEANUP, IRP_MJ_CLOSE] {
+        (*driver).major_function[major as usize] = Some(dispatch_open_close);
+    }
+    (*driver).major_function[IRP_MJ_READ as usize] = Some(dispatch_read);
+    (*driver).major_function[IRP_MJ_WRITE as usize] = Some(dispatch_write);
+    (*driver).major_function[IRP_MJ_DEVICE_CONTROL as usize] = Some(dispatch_device_control);
+    (*driver).driver_unload = Some(unload);
+    STATUS_SUCCESS
+}
+
+unsafe extern "win64" fn unload(driver: *mut DriverObject) {
+    let link_name = wide("\\DosDevices\\Null");
+    let mut link = UnicodeString { length: 0, maximum_length: 0, buffer: ptr::null_mut() };
+    RtlInitUnicodeString(&mut link, link_name.as_ptr());
+    IoDeleteSymbolicLink(&mut link);
+    IoDeleteDevice((*driver).device_object);
+}
+
+unsafe extern "win64" fn dispatch_open_close(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    if (*(*irp).current_stack_location).major_function == IRP_MJ_CREATE {
+        count(device, |stats| stats.opens += 1);
+    }
+    complete(irp, STATUS_SUCCESS, 0)
+}
+
+unsafe extern "win64" fn dispatch_read(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    count(device, |stats| stats.reads += 1);
+    complete(irp, STATUS_END_OF_FILE, 0)
+}
+
+unsafe extern "win64" fn dispatch_write(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    // Parameters.Write.Length
+    let length = (*(*irp).current_stack_location).parameters[0];
+    count(device, |stats| {
+        stats.writes += 1;
+        stats.bytes_written += length;
+    });
+    complete(irp, STATUS_SUCCESS, length)
+}
+
+unsafe extern "win64" fn dispatch_device_control(device: *mut DeviceObject, irp: *mut Irp) -> NTSTATUS {
+    let stack = &*(*irp).current_stack_location;
+    // Parameters.DeviceIoControl: OutputBufferLength, IoControlCode
+    let (output_length, code) = (stack.parameters[0] as usize, stack.parameters[2] as u32);
+    if code != IOCTL_NULL_GET_STATS {
+        return complete(irp, STATUS_INVALID_DEVICE_REQUEST, 0);
+    }
+    if output_length < size_of::<NullStats>() {
+        return complete(irp, STATUS_BUFFER_TOO_SMALL, 0);
+    }
+    let mut stats = NullStats::default();
+    count(device, |counted| stats = *counted);
+    (*irp).system_buffer.cast::<NullStats>().write_unaligned(stats);
+    complete(irp, STATUS_SUCCESS, size_of::<NullStats>() as u64)
+}
+
@@ -0,0 +1,332 @@
+// WDM structures, laid out as the x64 DDK declares them
+//
+// Drivers reach into these directly: IoGetCurrentIrpStackLocation,
+// IoMarkIrpPending and MmGetSystemAddressForMdlSafe are inline in the
+// headers, so every field a driver may touch sits at its Windows offset.
+// What only the Windows I/O manager uses is kept as opaque padding.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem::{offset_of, size_of};
+
+pub type NTSTATUS = i32;
+pub type KIRQL = u8;
+pub type KSPIN_LOCK = u64;
+
+pub const STATUS_SUCCESS: NTSTATUS = 0;
+pub const STATUS_PENDING: NTSTATUS = 0x103;
+pub const STATUS_INVALID_PARAMETER: NTSTATUS = 0xC000000Du32 as i32;
+pub const STATUS_NO_SUCH_DEVICE: NTSTATUS = 0xC000000Eu32 as i32;
+pub const STATUS_INVALID_DEVICE_REQUEST: NTSTATUS = 0xC0000010u32 as i32;
+pub const STATUS_END_OF_FILE: NTSTATUS = 0xC0000011u32 as i32;
+pub const STATUS_MORE_PROCESSING_REQUIRED: NTSTATUS = 0xC0000016u32 as i32;
+pub const STATUS_ACCESS_DENIED: NTSTATUS = 0xC0000022u32 as i32;
+pub const STATUS_BUFFER_TOO_SMALL: NTSTATUS = 0xC0000023u32 as i32;
+pub const STATUS_OBJECT_NAME_NOT_FOUND: NTSTATUS = 0xC0000034u32 as i32;
+pub const STATUS_OBJECT_NAME_COLLISION: NTSTATUS = 0xC0000035u32 as i32;
+pub const STATUS_INSUFFICIENT_RESOURCES: NTSTATUS = 0xC000009Au32 as i32;
+pub const STATUS_DEVICE_NOT_READY: NTSTATUS = 0xC00000A3u32 as i32;
+pub const STATUS_NOT_SUPPORTED: NTSTATUS = 0xC00000BBu32 as i32;
+pub const STATUS_IO_DEVICE_ERROR: NTSTATUS = 0xC0000185u32 as i32;
+pub const STATUS_DEVICE_BUSY: NTSTATUS = 0x80000011u32 as i32;
+
+pub fn nt_success(status: NTSTATUS) -> bool {
+    status >= 0
+}
+
+pub const PASSIVE_LEVEL: KIRQL = 0;
+pub const DISPATCH_LEVEL: KIRQL = 2;
+
+// Object types, in each object's Type field
+pub const IO_TYPE_DEVICE: i16 = 3;
+pub const IO_TYPE_DRIVER: i16 = 4;
+pub const IO_TYPE_FILE: i16 = 5;
+pub const IO_TYPE_IRP: i16 = 6;
+
+pub const IRP_MJ_CREATE: u8 = 0x00;
+pub const IRP_MJ_CLOSE: u8 = 0x02;
+pub const IRP_MJ_READ: u8 = 0x03;
+pub const IRP_MJ_WRITE: u8 = 0x04;
+pub const IRP_MJ_DEVICE_CONTROL: u8 = 0x0E;
+pub const IRP_MJ_INTERNAL_DEVICE_CONTROL: u8 = 0x0F;
+pub const IRP_MJ_CLEANUP: u8 = 0x12;
+pub const IRP_MJ_POWER: u8 = 0x16;
+pub const IRP_MJ_PNP: u8 = 0x1B;
+pub const IRP_MJ_MAXIMUM_FUNCTION: usize = 0x1B;
+
+// DEVICE_OBJECT Flags
+pub const DO_BUFFERED_IO: u32 = 0x0000_0004;
+pub const DO_EXCLUSIVE: u32 = 0x0000_0008;
+pub const DO_DIRECT_IO: u32 = 0x0000_0010;
+pub const DO_DEVICE_INITIALIZING: u32 = 0x0000_0080;
+
+// IO_STACK_LOCATION Control
+pub const SL_PENDING_RETURNED: u8 = 0x01;
+pub const SL_INVOKE_ON_CANCEL: u8 = 0x20;
+pub const SL_INVOKE_ON_SUCCESS: u8 = 0x40;
+pub const SL_INVOKE_ON_ERROR: u8 = 0x80;
+
+// The low two bits of an I/O control code: how its buffers are passed
+pub const METHOD_BUFFERED: u32 = 0;
+pub const METHOD_IN_DIRECT: u32 = 1;
+pub const METHOD_OUT_DIRECT: u32 = 2;
+pub const METHOD_NEITHER: u32 = 3;
+
+pub const MDL_MAPPED_TO_SYSTEM_VA: i16 = 0x0001;
+pub const MDL_SOURCE_IS_NONPAGED_POOL: i16 = 0x0004;
+
+pub const KERNEL_MODE: i8 = 0;
+pub const USER_MODE: i8 = 1;
+
+pub type DriverInitialize = unsafe extern "win64" fn(*mut DriverObject, *mut UnicodeString) -> NTSTATUS;
+pub type DriverUnload = unsafe extern "win64" fn(*mut DriverObject);
+pub type DriverAddDevice = unsafe extern "win64" fn(*mut DriverObject, *mut DeviceObject) -> NTSTATUS;
+pub type DriverDispatch = unsafe extern "win64" fn(*mut DeviceObject, *mut Irp) -> NTSTATUS;
+pub type IoCompletionRoutine = unsafe extern "win64" fn(*mut DeviceObject, *mut Irp, *mut c_void) -> NTSTATUS;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UnicodeString {
+    /// In bytes, without a terminator
+    pub length: u16,
+    pub maximum_length: u16,
+    pub buffer: *mut u16,
+}
+
+impl UnicodeString {
+    pub fn text(&self) -> String {
+        if self.buffer.is_null() {
+            return String::new();
+        }
+        let units = unsafe { core::slice::from_raw_parts(self.buffer, self.length as usize / 2) };
+        String::from_utf16_lossy(units)
+    }
+}
+
+/// A UNICODE_STRING with the buffer it points into
+pub struct OwnedUnicode {
+    _buffer: Vec<u16>,
+    pub string: UnicodeString,
+}
+
+impl OwnedUnicode {
+    pub fn new(text: &str) -> Self {
+        let mut buffer: Vec<u16> = text.encode_utf16().chain([0]).collect();
+        let string = UnicodeString {
+            length: ((buffer.len() - 1) * 2) as u16,
+            maximum_length: (buffer.len() * 2) as u16,
+            buffer: buffer.as_mut_ptr(),
+        };
+        OwnedUnicode { _buffer: buffer, string }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoStatusBlock {
+    pub status: NTSTATUS,
+    /// Bytes transferred, for reads, writes and I/O controls
+    pub information: u64,
+}
+
+#[repr(C)]
+pub struct DriverObject {
+    pub kind: i16,
+    pub size: i16,
+    /// The driver's devices, newest first, linked through NextDevice
+    pub device_object: *mut DeviceObject,
+    pub flags: u32,
+    pub driver_start: *mut c_void,
+    pub driver_size: u32,
+    pub driver_section: *mut c_void,
+    pub driver_extension: *mut DriverExtension,
+    pub driver_name: UnicodeString,
+    pub hardware_database: *mut UnicodeString,
+    pub fast_io_dispatch: *mut c_void,
+    pub driver_init: Option<DriverInitialize>,
+    pub driver_start_io: *mut c_void,
+    pub driver_unload: Option<DriverUnload>,
+    pub major_function: [Option<DriverDispatch>; IRP_MJ_MAXIMUM_FUNCTION + 1],
+}
+
+#[repr(C)]
+pub struct DriverExtension {
+    pub driver_object: *mut DriverObject,
+    pub add_device: Option<DriverAddDevice>,
+    pub count: u32,
+    pub service_key_name: UnicodeString,
+}
+
+#[repr(C, align(16))]
+pub struct DeviceObject {
+    pub kind: i16,
+    pub size: u16,
+    pub reference_count: i32,
+    pub driver_object: *mut DriverObject,
+    pub next_device: *mut DeviceObject,
+    /// The device attached on top of this one, if any
+    pub attached_device: *mut DeviceObject,
+    pub current_irp: *mut Irp,
+    pub timer: *mut c_void,
+    pub flags: u32,
+    pub characteristics: u32,
+    pub vpb: *mut c_void,
+    pub device_extension: *mut c_void,
+    pub device_type: u32,
+    /// Stack locations an IRP sent here needs: one for each device from
+    /// here down
+    pub stack_size: i8,
+    _queue: [u8; 0x4B],
+    pub alignment_requirement: u32,
+    _device_queue: [u8; 0x9C],
+    pub device_object_extension: *mut c_void,
+    _reserved: *mut c_void,
+}
+
+#[repr(C)]
+pub struct FileObject {
+    pub kind: i16,
+    pub size: i16,
+    pub device_object: *mut DeviceObject,
+    pub vpb: *mut c_void,
+    pub fs_context: *mut c_void,
+    pub fs_context2: *mut c_void,
+    _section: [u8; 0x28],
+    pub flags: u32,
+    pub file_name: UnicodeString,
+    pub current_byte_offset: i64,
+    _rest: [u8; 0x68],
+}
+
+#[repr(C)]
+pub struct Mdl {
+    pub next: *mut Mdl,
+    /// Of the MDL and the page frame numbers that follow it
+    pub size: i16,
+    pub mdl_flags: i16,
+    pub process: *mut c_void,
+    pub mapped_system_va: *mut c_void,
+    pub start_va: *mut c_void,
+    pub byte_count: u32,
+    pub byte_offset: u32,
+}
+
+#[repr(C, align(16))]
+pub struct Irp {
+    pub kind: i16,
+    /// Of the IRP and its stack locations
+    pub size: u16,
+    pub allocation_processor_number: u16,
+    _reserved: u16,
+    pub mdl_address: *mut Mdl,
+    pub flags: u32,
+    /// AssociatedIrp.SystemBuffer
+    pub system_buffer: *mut c_void,
+    pub thread_list_entry: [u64; 2],
+    pub io_status: IoStatusBlock,
+    pub requestor_mode: i8,
+    pub pending_returned: u8,
+    pub stack_count: i8,
+    /// 1-based; StackCount + 1 before the first IoCallDriver
+    pub current_location: i8,
+    pub cancel: u8,
+    pub cancel_irql: KIRQL,
+    pub apc_environment: i8,
+    pub allocation_flags: u8,
+    pub user_iosb: *mut IoStatusBlock,
+    pub user_event: *mut c_void,
+    pub overlay: [u64; 2],
+    pub cancel_routine: *mut c_void,
+    pub user_buffer: *mut c_void,
+    // Tail.Overlay
+    pub driver_context: [*mut c_void; 4],
+    pub thread: *mut c_void,
+    pub auxiliary_buffer: *mut u8,
+    pub list_entry: [u64; 2],
+    pub current_stack_location: *mut IoStackLocation,
+    pub original_file_object: *mut FileObject,
+    pub irp_extension: *mut c_void,
+}
+
+impl Irp {
+    /// IoGetNextIrpStackLocation: the location the next IoCallDriver gives
+    /// the driver below
+    pub fn next_stack_location(&self) -> *mut IoStackLocation {
+        self.current_stack_location.wrapping_sub(1)
+    }
+}
+
+#[repr(C)]
+pub struct IoStackLocation {
+    pub major_function: u8,
+    pub minor_function: u8,
+    pub flags: u8,
+    pub control: u8,
+    /// The Parameters union, which the accessors below read as the major
+    /// function lays it out
+    pub parameters: [u64; 4],
+    pub device_object: *mut DeviceObject,
+    pub file_object: *mut FileObject,
+    pub completion_routine: Option<IoCompletionRoutine>,
+    pub context: *mut c_void,
+}
+
+impl IoStackLocation {
+    /// Parameters.Read/Write: Length, Key, ByteOffset
+    pub fn set_read_write(&mut self, length: u32, byte_offset: i64) {
+        self.parameters = [length as u64, 0, byte_offset as u64, 0];
+    }
+
+    /// Parameters.DeviceIoControl: OutputBufferLength, InputBufferLength,
+    /// IoControlCode, Type3InputBuffer
+    pub fn set_device_control(&mut self, code: u32, input: *mut c_void, input_length: u32, output_length: u32) {
+        self.parameters = [output_length as u64, input_length as u64, code as u64, input as u64];
+    }
+}
+
+pub const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
+    (device_type << 16) | (access << 14) | (function << 2) | method
+}
+
+const _: () = {
+    assert!(offset_of!(DriverObject, device_object) == 0x08);
+    assert!(offset_of!(DriverObject, driver_extension) == 0x30);
+    assert!(offset_of!(DriverObject, driver_name) == 0x38);
+    assert!(offset_of!(DriverObject, driver_unload) == 0x68);
+    assert!(offset_of!(DriverObject, major_function) == 0x70);
+    assert!(size_of::<DriverObject>() == 0x150);
+    assert!(offset_of!(DriverExtension, add_device) == 0x08);
+    assert!(offset_of!(DriverExtension, service_key_name) == 0x18);
+    assert!(offset_of!(DeviceObject, driver_object) == 0x08);
+    assert!(offset_of!(DeviceObject, attached_device) == 0x18);
+    assert!(offset_of!(DeviceObject, flags) == 0x30);
+    assert!(offset_of!(DeviceObject, device_extension) == 0x40);
+    assert!(offset_of!(DeviceObject, stack_size) == 0x4C);
+    assert!(offset_of!(DeviceObject, alignment_requirement) == 0x98);
+    assert!(offset_of!(DeviceObject, device_object_extension) == 0x138);
+    assert!(size_of::<DeviceObject>() == 0x150);
+    assert!(offset_of!(FileObject, fs_context) == 0x18);
+    assert!(offset_of!(FileObject, file_name) == 0x58);
+    assert!(size_of::<FileObject>() == 0xD8);
+    assert!(offset_of!(Mdl, mdl_flags) == 0x0A);
+    assert!(offset_of!(Mdl, mapped_system_va) == 0x18);
+    assert!(offset_of!(Mdl, byte_count) == 0x28);
+    assert!(size_of::<Mdl>() == 0x30);
+    assert!(offset_of!(Irp, mdl_address) == 0x08);
+    assert!(offset_of!(Irp, system_buffer) == 0x18);
+    assert!(offset_of!(Irp, io_status) == 0x30);
+    assert!(offset_of!(Irp, pending_returned) == 0x41);
+    assert!(offset_of!(Irp, current_location) == 0x43);
+    assert!(offset_of!(Irp, user_iosb) == 0x48);
+    assert!(offset_of!(Irp, cancel_routine) == 0x68);
+    assert!(offset_of!(Irp, user_buffer) == 0x70);
+    assert!(offset_of!(Irp, driver_context) == 0x78);
+    assert!(offset_of!(Irp, current_stack_location) == 0xB8);
+    assert!(offset_of!(Irp, original_file_object) == 0xC0);
+    assert!(size_of::<Irp>() == 0xD0);
+    assert!(offset_of!(IoStackLocation, parameters) == 0x08);
+    assert!(offset_of!(IoStackLocation, device_object) == 0x28);
+    assert!(offset_of!(IoStackLocation, completion_routine) == 0x38);
+    assert!(size_of::<IoStackLocation>() == 0x48);
+};
@@ -3,74 +3,61 @@
 
 use alloc::vec::Vec;
 use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use spin::Mutex;
 use lazy_static::lazy_static;
 use bitflags::bitflags;
 
-// ACPI SRAT (System Resource Affinity Table) structures
-#[repr(C, packed)]
-struct SratHeader {
-    signature: [u8; 4],  // "SRAT"
-    length: u32,
-    revision: u8,
-    checksum: u8,
-    oem_id: [u8; 6],
-    oem_table_id: [u8; 8],
-    oem_revision: u32,
-    creator_id: u32,
-    creator_revision: u32,
-    reserved: u32,
-}
-
-#[repr(C, packed)]
-struct SratEntry {
-    entry_type: u8,
-    length: u8,
-}
-
-#[repr(C, packed)]
-struct SratProcessorAffinity {
-    entry_type: u8,     // 0x00
-    length: u8,         // 0x10
-    proximity_domain_low: u8,
-    apic_id: u8,
-    flags: u32,
-    local_sapic_eid: u8,
-    proximity_domain_high: [u8; 3],
-    clock_domain: u32,
-}
-
-#[repr(C, packed)]
-struct SratMemoryAffinity {
-    entry_type: u8,     // 0x01
-    length: u8,         // 0x28
-    proximity_domain: u32,
-    reserved1: u16,
-    base_address: u64,
-    length_bytes: u64,
-    reserved2: u32,
-    flags: u32,
-    reserved3: u64,
-}
+use crate::boot::cmdline::{Param, Value};
+
+// SRAT (System Resource Affinity Table) layout: the entries follow the
+// standard header, a revision word and eight reserved bytes
+const SRAT_ENTRIES: usize = 48;
+const SRAT_PROCESSOR_AFFINITY: u8 = 0;
+const SRAT_MEMORY_AFFINITY: u8 = 1;
+const SRAT_X2APIC_AFFINITY: u8 = 2;
+const SRAT_ENABLED: u32 = 1 << 0;
+
+// SLIT (System Locality Information Table): a locality count after the
+// standard header, then a count-by-count matrix of byte distances
+const SLIT_LOCALITIES: usize = 36;
+const SLIT_MATRIX: usize = 44;
+
+/// Distance of a node to itself, as SLIT gives it
+pub const LOCAL_DISTANCE: u32 = 10;
+/// Distance assumed between nodes when there is no SLIT
+pub const REMOTE_DISTANCE: u32 = 20;
+
+/// Nodes the kernel can tell apart; node masks are one bit per node
+pub const MAX_NODES: usize = 64;
+
+pub type NodeMask = u64;
 
 // NUMA node information
 #[derive(Debug, Clone)]
 pub struct NumaNode {
     pub id: u32,
+    /// The SRAT proximity domain the node was made from
+    pub proximity_domain: u32,
     pub cpus: Vec<u32>,
-    pub memory_start: u64,
-    pub memory_end: u64,
+    /// APIC IDs of the node's processors, including any not started
+    pub apic_ids: Vec<u32>,
+    /// Physical memory ranges, start inclusive and end exclusive
+    pub memory: Vec<(u64, u64)>,
     pub distance_map: BTreeMap<u32, u32>, // Distance to other nodes
 }
 
 impl NumaNode {
-    pub fn new(id: u32) -> Self {
+    pub fn new(id: u32, proximity_domain: u32) -> Self {
         Self {
             id,
+            proximity_domain,
             cpus: Vec::new(),
-            memory_start: 0,
-            memory_end: 0,
+            apic_ids: Vec::new(),
+            memory: Vec::new(),
             distance_map: BTreeMap::new(),
         }
     }
@@ -81,13 +68,17 @@ impl NumaNode {
         }
     }
     
-    pub fn set_memory_range(&mut self, start: u64, end: u64) {
-        self.memory_start = start;
-        self.memory_end = end;
+    pub fn add_memory_range(&mut self, start: u64, end: u64) {
+        self.memory.push((start, end));
+        self.memory.sort();
     }
     
     pub fn contains_address(&self, addr: u64) -> bool {
-        addr >= self.memory_start && addr < self.memory_end
+        self.memory.iter().any(|&(start, end)| addr >= start && addr < end)
+    }
+    
+    pub fn memory_size(&self) -> u64 {
+        self.memory.iter().map(|&(start, end)| end - start).sum()
     }
 }
 
@@ -95,6 +86,7 @@ impl NumaNode {
 pub struct NumaTopology {
     nodes: Vec<NumaNode>,
     cpu_to_node: BTreeMap<u32, u32>,
+    apic_to_node: BTreeMap<u32, u32>,
     node_count: u32,
 }
 
@@ -103,6 +95,7 @@ impl NumaTopology {
         Self {
             nodes: Vec::new(),
             cpu_to_node: BTreeMap::new(),
+            apic_to_node: BTreeMap::new(),
             node_count: 0,
         }
     }
@@ -111,25 +104,124 @@ impl NumaTopology {
         let mut topology = Self::new();
         
         // Try to parse ACPI SRAT table
-        if let Ok(()) = topology.parse_srat() {
-            crate::serial_println!("NUMA topology detected: {} nodes", topology.node_count);
-        } else {
-            // Fall back to single node
-            topology.create_single_node();
-            crate::serial_println!("NUMA: Single node system");
+        match topology.parse_srat() {
+            Ok(()) => {
+                topology.parse_slit();
+                topology.map_cpus();
+                crate::serial_println!("NUMA topology detected: {} nodes", topology.node_count);
+            }
+            Err(reason) => {
+                // Fall back to single node
+                topology = Self::new();
+                topology.create_single_node();
+                crate::serial_println!("NUMA: Single node system ({})", reason);
+            }
         }
         
         topology
     }
     
+    // The node for a proximity domain, made on first sight
+    fn node_for_domain(&mut self, domain: u32) -> Option<u32> {
+        if let Some(node) = self.nodes.iter().find(|n| n.proximity_domain == domain) {
+            return Some(node.id);
+        }
+        if self.nodes.len() >= MAX_NODES {
+            return None;
+        }
+        let id = self.nodes.len() as u32;
+        self.nodes.push(NumaNode::new(id, domain));
+        self.node_count += 1;
+        Some(id)
+    }
+    
     fn parse_srat(&mut self) -> Result<(), &'static str> {
-        // This would parse the ACPI SRAT table
-        // For now, return error to use fallback
-        Err("SRAT parsing not implemented")
+        let srat = acpi_table(b"SRAT").ok_or("no SRAT")?;
+        let mut offset = SRAT_ENTRIES;
+        
+        while offset + 2 <= srat.len() {
+            let entry_type = srat[offset];
+            let length = srat[offset + 1] as usize;
+            if length < 2 || offset + length > srat.len() {
+                return Err("malformed SRAT");
+            }
+            let entry = &srat[offset..offset + length];
+            
+            match entry_type {
+                SRAT_PROCESSOR_AFFINITY if length >= 16 && le32(entry, 4) & SRAT_ENABLED != 0 => {
+                    let domain = entry[2] as u32 | (le32(entry, 8) & 0xFFFF_FF00);
+                    let apic_id = entry[3] as u32;
+                    if let Some(node) = self.node_for_domain(domain) {
+                        self.nodes[node as usize].apic_ids.push(apic_id);
+                        self.apic_to_node.insert(apic_id, node);
+                    }
+                }
+                SRAT_X2APIC_AFFINITY if length >= 24 && le32(entry, 12) & SRAT_ENABLED != 0 => {
+                    let domain = le32(entry, 4);
+                    let apic_id = le32(entry, 8);
+                    if let Some(node) = self.node_for_domain(domain) {
+                        self.nodes[node as usize].apic_ids.push(apic_id);
+                        self.apic_to_node.insert(apic_id, node);
+                    }
+                }
+                SRAT_MEMORY_AFFINITY if length >= 40 && le32(entry, 28) & SRAT_ENABLED != 0 => {
+                    let domain = le32(entry, 2);
+                    let base = le64(entry, 8);
+                    let size = le64(entry, 16);
+                    if size != 0 {
+                        if let Some(node) = self.node_for_domain(domain) {
+                            self.nodes[node as usize].add_memory_range(base, base + size);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        
+        if self.nodes.is_empty() {
+            return Err("SRAT lists no nodes");
+        }
+        Ok(())
+    }
+    
+    // Distances between the nodes' proximity domains, if there is a SLIT
+    fn parse_slit(&mut self) {
+        let Some(slit) = acpi_table(b"SLIT") else {
+            return;
+        };
+        if slit.len() < SLIT_MATRIX {
+            return;
+        }
+        let localities = le64(slit, SLIT_LOCALITIES) as usize;
+        if slit.len() < SLIT_MATRIX + localities * localities {
+            return;
+        }
+        let domains: Vec<(u32, u32)> = self.nodes.iter().map(|n| (n.id, n.proximity_domain)).collect();
+        for node in &mut self.nodes {
+            for &(other, domain) in &domains {
+                let (from, to) = (node.proximity_domain as usize, domain as usize);
+                if from < localities && to < localities {
+                    node.distance_map.insert(other, slit[SLIT_MATRIX + from * localities + to] as u32);
+                }
+            }
+        }
+    }
+    
+    // Processors started so far, by their APIC IDs
+    fn map_cpus(&mut self) {
+        let smp = crate::smp::SMP_MANAGER.lock();
+        for cpu in 0..crate::smp::MAX_CPUS as u32 {
+            let Some(info) = smp.get_cpu(cpu) else { continue };
+            // Processors SRAT does not place go with the first node
+            let node = self.apic_to_node.get(&(info.apic_id as u32)).copied().unwrap_or(0);
+            self.nodes[node as usize].add_cpu(cpu);
+            self.cpu_to_node.insert(cpu, node);
+        }
     }
     
     fn create_single_node(&mut self) {
-        let mut node = NumaNode::new(0);
+        let mut node = NumaNode::new(0, 0);
         
         // Add all CPUs to node 0
         let cpu_count = crate::cpu::get_info().logical_cores as u32;
@@ -138,13 +230,25 @@ impl NumaTopology {
             self.cpu_to_node.insert(cpu, 0);
         }
         
-        // Set memory range (example: 0 to 4GB)
-        node.set_memory_range(0, 4 * 1024 * 1024 * 1024);
+        node.add_memory_range(0, u64::MAX);
         
         self.nodes.push(node);
         self.node_count = 1;
     }
     
+    pub fn node_count(&self) -> u32 {
+        self.node_count
+    }
+    
+    pub fn nodes(&self) -> &[NumaNode] {
+        &self.nodes
+    }
+    
+    /// Every node's bit
+    pub fn all_nodes(&self) -> NodeMask {
+        if self.node_count as usize >= MAX_NODES { !0 } else { (1 << self.node_count) - 1 }
+    }
+    
     pub fn get_node_for_cpu(&self, cpu_id: u32) -> Option<u32> {
         self.cpu_to_node.get(&cpu_id).copied()
     }
@@ -164,7 +268,7 @@ impl NumaTopology {
     
     pub fn get_distance(&self, from_node: u32, to_node: u32) -> u32 {
         if from_node == to_node {
-            return 10; // Local access
+            return LOCAL_DISTANCE;
         }
         
         if let Some(node) = self.get_node(from_node) {
@@ -173,8 +277,31 @@ impl NumaTopology {
             }
         }
         
-        20 // Default remote access cost
+        REMOTE_DISTANCE
     }
+    
+    /// Every node, nearest to `node` first, itself leading
+    pub fn nodes_by_distance(&self, node: u32) -> Vec<u32> {
+        let mut order: Vec<u32> = self.nodes.iter().map(|n| n.id).collect();
+        order.sort_by_key(|&other| (self.get_distance(node, other), other != node, other));
+        order
+    }
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// A table ACPI has found, whole, or None if it has not run
+fn acpi_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let acpi = crate::acpi::ACPI.lock();
+    let table = acpi.find_table(signature)?;
+    let start = (crate::memory::PHYS_MEM_OFFSET + table.address) as *const u8;
+    Some(unsafe { core::slice::from_raw_parts(start, table.length as usize) })
 }
 
 lazy_static! {
@@ -274,46 +401,155 @@ impl ProcessAffinity {
     }
 }
 
-// NUMA-aware memory allocation hints
-pub struct NumaAllocHint {
-    pub preferred_node: u32,
-    pub fallback_nodes: Vec<u32>,
-    pub interleave: bool,
-    pub local_only: bool,
+/// Where memory is allocated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemPolicy {
+    /// The node of the CPU allocating, then the nearest others
+    Local,
+    /// Only these nodes, the nearest to the allocating CPU first
+    Bind(NodeMask),
+    /// Round robin over these nodes, then whichever is nearest
+    Interleave(NodeMask),
 }
 
-impl NumaAllocHint {
-    pub fn local() -> Self {
-        let cpu_id = crate::cpu::get_cpu_id();
-        let node_id = NUMA_TOPOLOGY.get_node_for_cpu(cpu_id).unwrap_or(0);
-        
-        Self {
-            preferred_node: node_id,
-            fallback_nodes: Vec::new(),
-            interleave: false,
-            local_only: false,
+impl MemPolicy {
+    /// `local`, `interleave` over every node, or `bind:` or `interleave:`
+    /// with a list of node numbers such as `0,2-3`
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.split_once(':') {
+            None if text == "local" => Some(MemPolicy::Local),
+            None if text == "interleave" => Some(MemPolicy::Interleave(!0)),
+            Some(("bind", nodes)) => parse_node_list(nodes).map(MemPolicy::Bind),
+            Some(("interleave", nodes)) => parse_node_list(nodes).map(MemPolicy::Interleave),
+            _ => None,
         }
     }
     
-    pub fn on_node(node_id: u32) -> Self {
-        Self {
-            preferred_node: node_id,
-            fallback_nodes: Vec::new(),
-            interleave: false,
-            local_only: false,
+    /// Where the next allocation should come from
+    pub fn placement(&self) -> Placement {
+        let local = current_node();
+        let topology = &*NUMA_TOPOLOGY;
+        // Called with the frame allocator locked, so this must not allocate
+        let nearest = |mask: NodeMask| {
+            (0..topology.node_count())
+                .filter(|&node| mask & (1 << node) != 0)
+                .min_by_key(|&node| (topology.get_distance(local, node), node != local, node))
+                .unwrap_or(local)
+        };
+        match *self {
+            MemPolicy::Local => Placement { node: local, allowed: !0, interleaved: false },
+            MemPolicy::Bind(mask) => Placement { node: nearest(mask), allowed: mask, interleaved: false },
+            MemPolicy::Interleave(mask) => {
+                let mask = mask & topology.all_nodes();
+                if mask == 0 {
+                    return Placement { node: local, allowed: !0, interleaved: false };
+                }
+                // The n-th set bit, for the n-th interleaved allocation
+                let turn = INTERLEAVE_NEXT.fetch_add(1, Ordering::Relaxed) % mask.count_ones();
+                let mut bits = mask;
+                for _ in 0..turn {
+                    bits &= bits - 1;
+                }
+                Placement { node: bits.trailing_zeros(), allowed: !0, interleaved: true }
+            }
         }
     }
-    
-    pub fn interleaved(nodes: Vec<u32>) -> Self {
-        Self {
-            preferred_node: nodes.first().copied().unwrap_or(0),
-            fallback_nodes: nodes,
-            interleave: true,
-            local_only: false,
+}
+
+impl fmt::Display for MemPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemPolicy::Local => write!(f, "local"),
+            MemPolicy::Bind(mask) => write!(f, "bind:{}", node_list(mask)),
+            MemPolicy::Interleave(mask) if mask & NUMA_TOPOLOGY.all_nodes() == NUMA_TOPOLOGY.all_nodes() => {
+                write!(f, "interleave")
+            }
+            MemPolicy::Interleave(mask) => write!(f, "interleave:{}", node_list(mask)),
         }
     }
 }
 
+/// The node an allocation should come from, and the nodes it may fall back to
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub node: u32,
+    pub allowed: NodeMask,
+    /// Counted as an interleave hit when it lands on `node`
+    pub interleaved: bool,
+}
+
+static INTERLEAVE_NEXT: AtomicU32 = AtomicU32::new(0);
+
+// Each CPU's node, kept apart from the topology so the frame allocator can
+// read it without taking a lock
+static CPU_NODE: [AtomicU32; crate::smp::MAX_CPUS] = [const { AtomicU32::new(0) }; crate::smp::MAX_CPUS];
+
+/// The node of the CPU this runs on
+pub fn current_node() -> u32 {
+    node_of_cpu(crate::smp::current_cpu_id())
+}
+
+pub fn node_of_cpu(cpu: u32) -> u32 {
+    CPU_NODE.get(cpu as usize).map_or(0, |node| node.load(Ordering::Relaxed))
+}
+
+fn parse_node_list(text: &str) -> Option<NodeMask> {
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>().ok()?, last.parse::<usize>().ok()?),
+            None => {
+                let node = part.parse::<usize>().ok()?;
+                (node, node)
+            }
+        };
+        if first > last || last >= MAX_NODES {
+            return None;
+        }
+        for node in first..=last {
+            mask |= 1 << node;
+        }
+    }
+    Some(mask)
+}
+
+/// A node mask as a list like `0,2-3`, the form Linux prints
+pub fn node_list(mask: NodeMask) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut node = 0;
+    while node < MAX_NODES {
+        if mask & (1 << node) == 0 {
+            node += 1;
+            continue;
+        }
+        let first = node;
+        while node + 1 < MAX_NODES && mask & (1 << (node + 1)) != 0 {
+            node += 1;
+        }
+        parts.push(if first == node { format!("{}", first) } else { format!("{}-{}", first, node) });
+        node += 1;
+    }
+    parts.join(",")
+}
+
+impl Value for MemPolicy {
+    const KIND: &'static str = "local|interleave[:nodes]|bind:nodes";
+    
+    fn parse(text: &'static str) -> Option<Self> {
+        MemPolicy::parse(text)
+    }
+    
+    fn show(&self) -> String {
+        format!("{}", self)
+    }
+}
+
+static POLICY: Param<MemPolicy> = Param::new(
+    "numa.policy",
+    MemPolicy::Local,
+    "Default memory policy: local, interleave[:nodes] or bind:nodes",
+);
+
 // Inter-processor interrupt (IPI) optimization
 pub struct IpiOptimizer {
     ipi_count: BTreeMap<(u32, u32), AtomicU64>, // (from_cpu, to_cpu) -> count
@@ -518,12 +754,103 @@ pub fn get_optimal_cpu(hint: &SchedNumaHint) -> u32 {
     crate::cpu::get_cpu_id()
 }
 
-// Initialize NUMA subsystem
+// /proc/numa/topology, laid out like `numactl --hardware`
+fn proc_topology() -> String {
+    let topology = &*NUMA_TOPOLOGY;
+    let stats = crate::memory::frame_allocator::FRAME_ALLOCATOR.lock().node_stats();
+    let (all_total, all_free, _) = crate::memory::frame_allocator::memory_stats();
+    let mut text = format!("available: {} nodes ({})\n", topology.node_count(), node_list(topology.all_nodes()));
+    for node in topology.nodes() {
+        let cpus: Vec<String> = node.cpus.iter().map(|cpu| format!("{}", cpu)).collect();
+        text += &format!("node {} cpus: {}\n", node.id, cpus.join(" "));
+        let (total, free) = match stats.iter().find(|stats| stats.node == node.id) {
+            Some(stats) => (stats.total_frames as u64 * 4, stats.free_frames as u64 * 4),
+            None => (all_total as u64 * 4, all_free as u64 * 4),
+        };
+        text += &format!("node {} size: {} MB\n", node.id, total / 1024);
+        text += &format!("node {} free: {} MB\n", node.id, free / 1024);
+    }
+    text += "node distances:\nnode ";
+    for to in topology.nodes() {
+        text += &format!("{:>4}", to.id);
+    }
+    for from in topology.nodes() {
+        text += &format!("\n{:>4}:", from.id);
+        for to in topology.nodes() {
+            text += &format!("{:>4}", topology.get_distance(from.id, to.id));
+        }
+    }
+    text + "\n"
+}
+
+// /proc/numa/numastat, a column per node like `numastat`
+fn proc_numastat() -> String {
+    let stats = crate::memory::frame_allocator::FRAME_ALLOCATOR.lock().node_stats();
+    let mut text = format!("{:<16}", "");
+    for node in &stats {
+        text += &format!("{:>14}", format!("node{}", node.node));
+    }
+    let rows: [(&str, fn(&crate::memory::frame_allocator::NodeStats) -> u64); 6] = [
+        ("numa_hit", |node| node.hit),
+        ("numa_miss", |node| node.miss),
+        ("numa_foreign", |node| node.foreign),
+        ("interleave_hit", |node| node.interleave_hit),
+        ("local_node", |node| node.local_node),
+        ("other_node", |node| node.other_node),
+    ];
+    for (name, value) in rows {
+        text += &format!("\n{:<16}", name);
+        for node in &stats {
+            text += &format!("{:>14}", value(node));
+        }
+    }
+    text + "\n"
+}
+
+fn proc_policy() -> String {
+    format!("{}\n", crate::memory::frame_allocator::FRAME_ALLOCATOR.lock().policy())
+}
+
+/// Tag CPUs with their nodes, hand the nodes' memory to the frame
+/// allocator with the default policy, and publish /proc/numa
 pub fn init() {
-    // Force lazy static initialization
-    let _ = &*NUMA_TOPOLOGY;
+    let topology = &*NUMA_TOPOLOGY;
     let _ = &*IPI_OPTIMIZER;
     let _ = &*NUMA_STATS;
     
-    crate::serial_println!("NUMA subsystem initialized");
-}
\ No newline at end of file
+    {
+        let mut smp = crate::smp::SMP_MANAGER.lock();
+        for node in topology.nodes() {
+            for &cpu in &node.cpus {
+                if let Some(slot) = CPU_NODE.get(cpu as usize) {
+                    slot.store(node.id, Ordering::Relaxed);
+                }
+                if let Some(info) = smp.get_cpu_mut(cpu) {
+                    info.numa_node = node.id as u8;
+                }
+            }
+        }
+    }
+    
+    let memory: Vec<crate::memory::frame_allocator::NodeMemory> = topology
+        .nodes()
+        .iter()
+        .map(|node| crate::memory::frame_allocator::NodeMemory {
+            node: node.id,
+            ranges: node.memory.clone(),
+            fallback: topology.nodes_by_distance(node.id),
+        })
+        .collect();
+    let policy = POLICY.get();
+    {
+        let mut frames = crate::memory::frame_allocator::FRAME_ALLOCATOR.lock();
+        frames.set_nodes(&memory);
+        frames.set_policy(policy);
+    }
+    
+    crate::fs::procfs::register("numa/topology", proc_topology);
+    crate::fs::procfs::register("numa/numastat", proc_numastat);
+    crate::fs::procfs::register("numa/policy", proc_policy);
+    
+    crate::serial_println!("NUMA subsystem initialized: {} node(s), policy {}", topology.node_count(), policy);
+}
@@ -14,6 +14,8 @@ use core::mem;
 use crate::{println, serial_println};
 use crate::memory::PHYS_MEM_OFFSET;
 use crate::drivers::disk::{DiskDriver, DiskError, DiskInfo};
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER, PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_NVME};
 
 // NVMe Constants
 pub const NVME_CAP: usize = 0x0000;        // Controller Capabilities
@@ -791,4 +793,55 @@ pub fn init() -> Result<(), &'static str> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+// Register base of an NVMe function: BAR0 and BAR1 together, as it is
+// always 64-bit
+fn register_base(device: &PciDevice) -> Option<u64> {
+    let base = (device.bars[0] as u64 & !0xF) | ((device.bars[1] as u64) << 32);
+    if base == 0 { None } else { Some(base) }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+/// Binds the NVMe controllers found on PCI
+pub static PCI_DRIVER: NvmePciDriver = NvmePciDriver;
+
+pub struct NvmePciDriver;
+
+impl Driver for NvmePciDriver {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+    
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+    
+    fn id_table(&self) -> &'static [Match] {
+        &[Match::PciClass { class: PCI_CLASS_STORAGE, subclass: PCI_SUBCLASS_STORAGE_NVME, prog_if: Some(0x02) }]
+    }
+    
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        let base = register_base(&pci).ok_or(ProbeError::Failed("no register BAR"))?;
+        PCIE_CONTROLLER.lock().enable_device(&pci);
+        let mut controller = unsafe { NvmeController::new(base) }.map_err(ProbeError::Failed)?;
+        controller.init().map_err(ProbeError::Failed)?;
+        serial_println!("NVMe: Controller at 0x{:x} initialized", base);
+        NVME_CONTROLLERS.lock().push(controller);
+        Ok(())
+    }
+    
+    fn remove(&self, device: &Device) {
+        if let Ident::Pci { location, .. } = device.ident {
+            let base = pci_function(location).as_ref().and_then(register_base);
+            NVME_CONTROLLERS.lock().retain(|controller| Some(controller.base_addr) != base);
+        }
+    }
+}
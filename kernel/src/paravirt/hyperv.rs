@@ -0,0 +1,354 @@
+//! Hyper-V enlightenments: hypercall page, reference TSC page, SynIC and
+//! synthetic timers, as described in the Hypervisor Top-Level Functional
+//! Specification
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::{current_cpu, shared_page, SINT_VECTOR};
+use crate::cpu::{read_msr, write_msr};
+use crate::smp::MAX_CPUS;
+use crate::sync::Mutex;
+
+const CPUID_INTERFACE: u32 = 0x4000_0001;
+const CPUID_FEATURES: u32 = 0x4000_0003;
+
+// "Hv#1"
+const INTERFACE_SIGNATURE: u32 = 0x3123_7648;
+
+// Partition privileges, CPUID 0x40000003 EAX
+const ACCESS_TIME_REF_COUNT: u32 = 1 << 1;
+const ACCESS_SYNIC: u32 = 1 << 2;
+const ACCESS_SYNTHETIC_TIMERS: u32 = 1 << 3;
+const ACCESS_APIC_MSRS: u32 = 1 << 4;
+const ACCESS_HYPERCALL: u32 = 1 << 5;
+const ACCESS_REFERENCE_TSC: u32 = 1 << 9;
+const ACCESS_FREQUENCY_MSRS: u32 = 1 << 11;
+
+// Feature flags, CPUID 0x40000003 EDX
+const FEATURE_FREQUENCY_MSRS: u32 = 1 << 8;
+const FEATURE_DIRECT_SYNTHETIC_TIMERS: u32 = 1 << 19;
+
+const MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const MSR_HYPERCALL: u32 = 0x4000_0001;
+const MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const MSR_TSC_FREQUENCY: u32 = 0x4000_0022;
+const MSR_APIC_FREQUENCY: u32 = 0x4000_0023;
+const MSR_SCONTROL: u32 = 0x4000_0080;
+const MSR_SIEFP: u32 = 0x4000_0082;
+const MSR_SIMP: u32 = 0x4000_0083;
+const MSR_EOM: u32 = 0x4000_0084;
+const MSR_SINT0: u32 = 0x4000_0090;
+const MSR_STIMER0_CONFIG: u32 = 0x4000_00b0;
+const MSR_STIMER0_COUNT: u32 = 0x4000_00b1;
+
+// Open-source OS (bit 63), vendor-defined type, version 1
+const GUEST_OS_ID: u64 = (1 << 63) | (0x7f << 56) | (1 << 16);
+
+const ENABLE: u64 = 1 << 0;
+
+const SINT_MASKED: u64 = 1 << 16;
+const SINT_AUTO_EOI: u64 = 1 << 17;
+
+const STIMER_ENABLE: u64 = 1 << 0;
+const STIMER_PERIODIC: u64 = 1 << 1;
+const STIMER_AUTO_ENABLE: u64 = 1 << 3;
+const STIMER_DIRECT_MODE: u64 = 1 << 12;
+
+// Stimer 0 drives the periodic tick, stimer 1 one-shot deadlines
+const TICK_TIMER: u32 = 0;
+const DEADLINE_TIMER: u32 = 1;
+
+const HVCALL_POST_MESSAGE: u64 = 0x005c;
+const HV_STATUS_INSUFFICIENT_BUFFERS: u16 = 0x13;
+
+/// Payload bytes in one SynIC message
+pub const MESSAGE_PAYLOAD: usize = 240;
+const MESSAGE_SIZE: usize = 256;
+
+// SynIC message slot header
+const MESSAGE_TYPE_NONE: u32 = 0;
+const MESSAGE_FLAG_PENDING: u8 = 1 << 0;
+
+static PRIVILEGES: AtomicU32 = AtomicU32::new(0);
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+static HYPERCALL_PAGE: AtomicU64 = AtomicU64::new(0);
+static REFERENCE_TSC_PAGE: AtomicU64 = AtomicU64::new(0);
+
+// Per CPU: SynIC message and event flag pages, 0 until SynIC is on there
+static MESSAGE_PAGES: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static EVENT_PAGES: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+// Input page for hypercalls, serialized by the lock
+static HYPERCALL_INPUT: Mutex<u64> = Mutex::new(0);
+
+fn has(privilege: u32) -> bool {
+    PRIVILEGES.load(Ordering::Relaxed) & privilege != 0
+}
+
+fn has_feature(feature: u32) -> bool {
+    FEATURES.load(Ordering::Relaxed) & feature != 0
+}
+
+/// Identify to the hypervisor and map the shared pages; false if the
+/// partition does not speak the Hyper-V interface
+pub fn init() -> bool {
+    if core::arch::x86_64::__cpuid(CPUID_INTERFACE).eax != INTERFACE_SIGNATURE {
+        return false;
+    }
+    let features = core::arch::x86_64::__cpuid(CPUID_FEATURES);
+    PRIVILEGES.store(features.eax, Ordering::Relaxed);
+    FEATURES.store(features.edx, Ordering::Relaxed);
+
+    if has(ACCESS_HYPERCALL) {
+        // The hypercall page must be enabled after the guest OS ID is set
+        write_msr(MSR_GUEST_OS_ID, GUEST_OS_ID);
+        let page = shared_page();
+        write_msr(MSR_HYPERCALL, page | ENABLE);
+        HYPERCALL_PAGE.store(page, Ordering::Relaxed);
+        *HYPERCALL_INPUT.lock() = shared_page();
+    }
+
+    if has(ACCESS_REFERENCE_TSC) {
+        let page = shared_page();
+        write_msr(MSR_REFERENCE_TSC, page | ENABLE);
+        REFERENCE_TSC_PAGE.store(page, Ordering::Relaxed);
+    }
+
+    init_cpu();
+
+    crate::serial_println!(
+        "[PARAVIRT] Hyper-V: hypercalls {}, reference TSC {}, SynIC {}, synthetic timers {}",
+        on_off(has(ACCESS_HYPERCALL)),
+        on_off(has(ACCESS_REFERENCE_TSC)),
+        on_off(has(ACCESS_SYNIC)),
+        if !has(ACCESS_SYNTHETIC_TIMERS) {
+            "off"
+        } else if has_feature(FEATURE_DIRECT_SYNTHETIC_TIMERS) {
+            "direct"
+        } else {
+            "message mode (unused)"
+        },
+    );
+    true
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Turn on this CPU's SynIC, with VMBus messages on `vmbus::MESSAGE_SINT`
+pub fn init_cpu() {
+    if !has(ACCESS_SYNIC) {
+        return;
+    }
+    let cpu = current_cpu();
+    if MESSAGE_PAGES[cpu].load(Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    let messages = shared_page();
+    let events = shared_page();
+    write_msr(MSR_SIMP, messages | ENABLE);
+    write_msr(MSR_SIEFP, events | ENABLE);
+    for sint in 0..16 {
+        let value = if sint == super::vmbus::MESSAGE_SINT {
+            SINT_VECTOR as u64 | SINT_AUTO_EOI
+        } else {
+            SINT_MASKED
+        };
+        write_msr(MSR_SINT0 + sint, value);
+    }
+    write_msr(MSR_SCONTROL, ENABLE);
+
+    EVENT_PAGES[cpu].store(events, Ordering::Relaxed);
+    MESSAGE_PAGES[cpu].store(messages, Ordering::Relaxed);
+}
+
+/// Partition reference time in 100 ns units
+pub fn reference_time() -> Option<u64> {
+    let page = REFERENCE_TSC_PAGE.load(Ordering::Relaxed);
+    if page != 0 {
+        if let Some(time) = read_reference_tsc_page(page) {
+            return Some(time);
+        }
+    }
+    // Slower, but always valid
+    has(ACCESS_TIME_REF_COUNT).then(|| read_msr(MSR_TIME_REF_COUNT))
+}
+
+/// Reference time from the TSC page: ((tsc * scale) >> 64) + offset,
+/// retried while the hypervisor is updating it. None while the page is
+/// marked invalid, e.g. across a live migration.
+fn read_reference_tsc_page(page: u64) -> Option<u64> {
+    let sequence_ptr = page as *const AtomicU32;
+    let scale_ptr = (page + 8) as *const u64;
+    let offset_ptr = (page + 16) as *const i64;
+
+    loop {
+        let sequence = unsafe { (*sequence_ptr).load(Ordering::Acquire) };
+        if sequence == 0 {
+            return None;
+        }
+        let (scale, offset) = unsafe { (scale_ptr.read_volatile(), offset_ptr.read_volatile()) };
+        let tsc = crate::timer::rdtsc();
+        if unsafe { (*sequence_ptr).load(Ordering::Acquire) } == sequence {
+            let scaled = ((tsc as u128 * scale as u128) >> 64) as u64;
+            return Some(scaled.wrapping_add(offset as u64));
+        }
+    }
+}
+
+pub fn clock_ns() -> Option<u64> {
+    reference_time().map(|time| time * 100)
+}
+
+pub fn tsc_frequency() -> Option<u64> {
+    frequency_msrs().then(|| read_msr(MSR_TSC_FREQUENCY))
+}
+
+/// Local APIC timer frequency, sparing its calibration against the PIT
+pub fn apic_frequency() -> Option<u64> {
+    frequency_msrs().then(|| read_msr(MSR_APIC_FREQUENCY))
+}
+
+fn frequency_msrs() -> bool {
+    has(ACCESS_FREQUENCY_MSRS) && has_feature(FEATURE_FREQUENCY_MSRS)
+}
+
+fn direct_timers() -> bool {
+    has(ACCESS_SYNTHETIC_TIMERS) && has_feature(FEATURE_DIRECT_SYNTHETIC_TIMERS) && has(ACCESS_APIC_MSRS)
+}
+
+fn program_timer(timer: u32, vector: u8, periodic: bool, count_100ns: u64) {
+    let mut config = STIMER_ENABLE | STIMER_DIRECT_MODE | (vector as u64) << 4;
+    if periodic {
+        config |= STIMER_PERIODIC;
+    } else {
+        // Re-armed by writing the count
+        config |= STIMER_AUTO_ENABLE;
+    }
+    write_msr(MSR_STIMER0_CONFIG + 2 * timer, config);
+    write_msr(MSR_STIMER0_COUNT + 2 * timer, count_100ns.max(1));
+}
+
+/// Drive the periodic tick on `vector` from a synthetic timer in direct
+/// mode, delivered through the local APIC like its own timer. False if
+/// the partition cannot.
+pub fn start_periodic_timer(vector: u8, period_ns: u64) -> bool {
+    if !direct_timers() {
+        return false;
+    }
+    program_timer(TICK_TIMER, vector, true, period_ns / 100);
+    true
+}
+
+/// Fire `vector` once after `delay_ns`; false if there are no direct
+/// synthetic timers
+pub fn program_oneshot(vector: u8, delay_ns: u64) -> bool {
+    if !direct_timers() {
+        return false;
+    }
+    // One-shot counts are absolute reference times
+    let Some(now) = reference_time() else {
+        return false;
+    };
+    program_timer(DEADLINE_TIMER, vector, false, now + delay_ns / 100);
+    true
+}
+
+/// Send a message to a port through HvPostMessage, retrying while the
+/// hypervisor's buffers are full. Err holds the hypercall status.
+pub fn post_message(connection_id: u32, message_type: u32, payload: &[u8]) -> Result<(), u16> {
+    let page = HYPERCALL_PAGE.load(Ordering::Relaxed);
+    if page == 0 || payload.len() > MESSAGE_PAYLOAD {
+        return Err(u16::MAX);
+    }
+
+    let input = HYPERCALL_INPUT.lock();
+    let base = *input as *mut u8;
+    unsafe {
+        (base as *mut u32).write_volatile(connection_id);
+        (base.add(4) as *mut u32).write_volatile(0);
+        (base.add(8) as *mut u32).write_volatile(message_type);
+        (base.add(12) as *mut u32).write_volatile(payload.len() as u32);
+        core::ptr::copy_nonoverlapping(payload.as_ptr(), base.add(16), payload.len());
+    }
+
+    for _ in 0..100 {
+        let status = unsafe { hypercall(page, HVCALL_POST_MESSAGE, *input, 0) } as u16;
+        match status {
+            0 => return Ok(()),
+            HV_STATUS_INSUFFICIENT_BUFFERS => core::hint::spin_loop(),
+            status => return Err(status),
+        }
+    }
+    Err(HV_STATUS_INSUFFICIENT_BUFFERS)
+}
+
+/// Slow hypercall with its input and output in memory
+unsafe fn hypercall(page: u64, control: u64, input: u64, output: u64) -> u64 {
+    let status: u64;
+    asm!(
+        "call {page}",
+        page = in(reg) page,
+        in("rcx") control,
+        in("rdx") input,
+        in("r8") output,
+        lateout("rax") status,
+        clobber_abi("C"),
+    );
+    status
+}
+
+/// Take the message waiting in `sint`'s slot on this CPU, if any, as its
+/// type and payload
+pub fn take_message(sint: u32) -> Option<(u32, [u8; MESSAGE_PAYLOAD])> {
+    let page = MESSAGE_PAGES[current_cpu()].load(Ordering::Relaxed);
+    if page == 0 {
+        return None;
+    }
+
+    let slot = page as usize + sint as usize * MESSAGE_SIZE;
+    let message_type = unsafe { &*(slot as *const AtomicU32) };
+    let kind = message_type.load(Ordering::Acquire);
+    if kind == MESSAGE_TYPE_NONE {
+        return None;
+    }
+
+    let mut payload = [0u8; MESSAGE_PAYLOAD];
+    let flags = unsafe {
+        core::ptr::copy_nonoverlapping((slot + 16) as *const u8, payload.as_mut_ptr(), MESSAGE_PAYLOAD);
+        ((slot + 5) as *const u8).read_volatile()
+    };
+    // Free the slot, then ask for the next message if one is queued
+    message_type.store(MESSAGE_TYPE_NONE, Ordering::Release);
+    if flags & MESSAGE_FLAG_PENDING != 0 {
+        write_msr(MSR_EOM, 0);
+    }
+    Some((kind, payload))
+}
+
+/// What is in use, for the shell
+pub struct Status {
+    pub hypercalls: bool,
+    pub reference_tsc: bool,
+    pub synic: bool,
+    pub direct_timers: bool,
+    pub frequency_msrs: bool,
+}
+
+pub fn status() -> Status {
+    Status {
+        hypercalls: HYPERCALL_PAGE.load(Ordering::Relaxed) != 0,
+        reference_tsc: REFERENCE_TSC_PAGE.load(Ordering::Relaxed) != 0,
+        synic: MESSAGE_PAGES[current_cpu()].load(Ordering::Relaxed) != 0,
+        direct_timers: direct_timers(),
+        frequency_msrs: frequency_msrs(),
+    }
+}
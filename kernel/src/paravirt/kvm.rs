@@ -0,0 +1,165 @@
+//! KVM enlightenments: kvmclock and paravirtual EOI
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use super::current_cpu;
+use crate::cpu::write_msr;
+use crate::smp::MAX_CPUS;
+
+const CPUID_FEATURES: u32 = 0x4000_0001;
+
+const FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const FEATURE_PV_EOI: u32 = 1 << 6;
+const FEATURE_CLOCKSOURCE_STABLE: u32 = 1 << 24;
+
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+
+const ENABLE: u64 = 1 << 0;
+
+// Set by KVM when the EOI of the interrupt being injected may be skipped
+const PV_EOI_PENDING: u32 = 1 << 0;
+
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+
+// Per CPU: address of its PvClock and PV EOI word, 0 until registered
+static CLOCKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static EOI_WORDS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// `struct pvclock_vcpu_time_info`, updated by KVM
+#[repr(C, align(32))]
+struct PvClock {
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+fn has(feature: u32) -> bool {
+    FEATURES.load(Ordering::Relaxed) & feature != 0
+}
+
+pub fn init() -> bool {
+    let features = core::arch::x86_64::__cpuid(CPUID_FEATURES).eax;
+    FEATURES.store(features, Ordering::Relaxed);
+    init_cpu();
+
+    crate::serial_println!(
+        "[PARAVIRT] KVM: kvmclock {}{}, PV EOI {}",
+        if has(FEATURE_CLOCKSOURCE2) { "on" } else { "off" },
+        if has(FEATURE_CLOCKSOURCE_STABLE) { " (stable)" } else { "" },
+        if has(FEATURE_PV_EOI) { "on" } else { "off" },
+    );
+    true
+}
+
+/// Register this CPU's clock and EOI word with KVM
+pub fn init_cpu() {
+    let cpu = current_cpu();
+
+    if has(FEATURE_CLOCKSOURCE2) && CLOCKS[cpu].load(Ordering::Relaxed) == 0 {
+        let clock = Box::leak(Box::new(PvClock {
+            version: 0,
+            pad0: 0,
+            tsc_timestamp: 0,
+            system_time: 0,
+            tsc_to_system_mul: 0,
+            tsc_shift: 0,
+            flags: 0,
+            pad: [0; 2],
+        })) as *mut PvClock as u64;
+        write_msr(MSR_KVM_SYSTEM_TIME_NEW, clock | ENABLE);
+        CLOCKS[cpu].store(clock, Ordering::Release);
+    }
+
+    if has(FEATURE_PV_EOI) && EOI_WORDS[cpu].load(Ordering::Relaxed) == 0 {
+        let word = Box::leak(Box::new(AtomicU32::new(0))) as *mut AtomicU32 as u64;
+        write_msr(MSR_KVM_PV_EOI_EN, word | ENABLE);
+        EOI_WORDS[cpu].store(word, Ordering::Release);
+    }
+}
+
+/// Consistent copy of this CPU's clock: retried while the version is odd
+/// (KVM is writing it) or changed underneath the read
+fn read_clock() -> Option<(PvClock, u64)> {
+    let address = CLOCKS[current_cpu()].load(Ordering::Acquire);
+    if address == 0 {
+        return None;
+    }
+    let clock = address as *const PvClock;
+
+    loop {
+        let version = unsafe { core::ptr::addr_of!((*clock).version).read_volatile() };
+        if version & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        core::sync::atomic::fence(Ordering::Acquire);
+        let snapshot = unsafe { clock.read_volatile() };
+        let tsc = crate::timer::rdtsc();
+        core::sync::atomic::fence(Ordering::Acquire);
+        if unsafe { core::ptr::addr_of!((*clock).version).read_volatile() } == version {
+            return Some((snapshot, tsc));
+        }
+    }
+}
+
+/// Nanoseconds since the host's reference point
+pub fn clock_ns() -> Option<u64> {
+    let (clock, tsc) = read_clock()?;
+    let mut delta = tsc.wrapping_sub(clock.tsc_timestamp);
+    if clock.tsc_shift < 0 {
+        delta >>= -clock.tsc_shift;
+    } else {
+        delta <<= clock.tsc_shift;
+    }
+    let scaled = ((delta as u128 * clock.tsc_to_system_mul as u128) >> 32) as u64;
+    Some(clock.system_time.wrapping_add(scaled))
+}
+
+/// TSC frequency implied by the kvmclock scale factor
+pub fn tsc_frequency() -> Option<u64> {
+    let (clock, _) = read_clock()?;
+    if clock.tsc_to_system_mul == 0 {
+        return None;
+    }
+    let mut hz = (1_000_000_000u128 << 32) / clock.tsc_to_system_mul as u128;
+    if clock.tsc_shift < 0 {
+        hz <<= -clock.tsc_shift;
+    } else {
+        hz >>= clock.tsc_shift;
+    }
+    Some(hz as u64)
+}
+
+/// Claim the EOI KVM offered for the current interrupt; true if the APIC
+/// write can be skipped
+pub fn pv_eoi() -> bool {
+    let address = EOI_WORDS[current_cpu()].load(Ordering::Relaxed);
+    if address == 0 {
+        return false;
+    }
+    let word = unsafe { &*(address as *const AtomicU32) };
+    word.fetch_and(!PV_EOI_PENDING, Ordering::SeqCst) & PV_EOI_PENDING != 0
+}
+
+/// What is in use on this CPU, for the shell
+pub struct Status {
+    pub kvmclock: bool,
+    pub stable: bool,
+    pub pv_eoi: bool,
+}
+
+pub fn status() -> Status {
+    let cpu = current_cpu();
+    Status {
+        kvmclock: CLOCKS[cpu].load(Ordering::Relaxed) != 0,
+        stable: has(FEATURE_CLOCKSOURCE_STABLE),
+        pv_eoi: EOI_WORDS[cpu].load(Ordering::Relaxed) != 0,
+    }
+}
@@ -0,0 +1,150 @@
+//! Paravirtual facilities for running as a guest
+//!
+//! Under Hyper-V and KVM, emulated hardware timers and APIC writes each cost
+//! a VM exit. When CPUID names one of them, the kernel switches to what the
+//! hypervisor offers instead: the Hyper-V reference TSC page, synthetic
+//! timers and SynIC (`hyperv`), with VMBus channels enumerated over it
+//! (`vmbus`), or kvmclock and PV EOI (`kvm`). Everything falls back to the
+//! bare-metal paths when a facility is missing.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub mod hyperv;
+pub mod kvm;
+pub mod vmbus;
+
+/// Vector SynIC messages arrive on (VMBus), with auto-EOI
+pub const SINT_VECTOR: u8 = 0xf3;
+
+// CPUID leaf 1 ECX: running under a hypervisor
+const CPUID_HYPERVISOR_PRESENT: u32 = 1 << 31;
+const CPUID_HYPERVISOR_BASE: u32 = 0x4000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    HyperV,
+    Kvm,
+    /// Something else, by its CPUID vendor signature
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    pub fn name(&self) -> &str {
+        match self {
+            Hypervisor::HyperV => "Microsoft Hyper-V",
+            Hypervisor::Kvm => "KVM",
+            Hypervisor::Other(signature) => {
+                let len = signature.iter().position(|&b| b == 0).unwrap_or(signature.len());
+                core::str::from_utf8(&signature[..len]).unwrap_or("unknown")
+            }
+        }
+    }
+}
+
+// 0 bare metal or not probed, 1 Hyper-V, 2 KVM, 3 other
+static HYPERVISOR: AtomicU8 = AtomicU8::new(0);
+
+/// Hypervisor named by CPUID, if any
+pub fn detect() -> Option<Hypervisor> {
+    if core::arch::x86_64::__cpuid(1).ecx & CPUID_HYPERVISOR_PRESENT == 0 {
+        return None;
+    }
+
+    let leaf = core::arch::x86_64::__cpuid(CPUID_HYPERVISOR_BASE);
+    let mut signature = [0u8; 12];
+    signature[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    signature[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+
+    Some(match &signature {
+        b"Microsoft Hv" => Hypervisor::HyperV,
+        b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+        _ => Hypervisor::Other(signature),
+    })
+}
+
+pub fn hypervisor() -> Option<Hypervisor> {
+    match HYPERVISOR.load(Ordering::Relaxed) {
+        1 => Some(Hypervisor::HyperV),
+        2 => Some(Hypervisor::Kvm),
+        3 => detect(),
+        _ => None,
+    }
+}
+
+/// Detect the hypervisor and set up its facilities on the boot CPU
+pub fn init() {
+    let Some(hypervisor) = detect() else {
+        crate::serial_println!("[PARAVIRT] Running on bare metal");
+        return;
+    };
+    crate::serial_println!("[PARAVIRT] Running under {}", hypervisor.name());
+
+    let kind = match hypervisor {
+        Hypervisor::HyperV if hyperv::init() => 1,
+        Hypervisor::Kvm if kvm::init() => 2,
+        _ => 3,
+    };
+    HYPERVISOR.store(kind, Ordering::SeqCst);
+
+    if kind == 1 {
+        if let Err(e) = vmbus::init() {
+            crate::serial_println!("[PARAVIRT] VMBus unavailable: {}", e);
+        }
+    }
+}
+
+/// Per-CPU setup for application processors
+pub fn init_cpu() {
+    match HYPERVISOR.load(Ordering::Relaxed) {
+        1 => hyperv::init_cpu(),
+        2 => kvm::init_cpu(),
+        _ => {}
+    }
+}
+
+/// Nanoseconds from the hypervisor's clock, if it offers one
+pub fn clock_ns() -> Option<u64> {
+    match HYPERVISOR.load(Ordering::Relaxed) {
+        1 => hyperv::clock_ns(),
+        2 => kvm::clock_ns(),
+        _ => None,
+    }
+}
+
+/// TSC frequency as the hypervisor reports it, sparing a PIT calibration
+pub fn tsc_frequency() -> Option<u64> {
+    match HYPERVISOR.load(Ordering::Relaxed) {
+        1 => hyperv::tsc_frequency(),
+        2 => kvm::tsc_frequency(),
+        _ => None,
+    }
+}
+
+/// Complete the current interrupt without touching the APIC if the
+/// hypervisor allows it; false means an APIC EOI is still needed
+pub fn eoi() -> bool {
+    HYPERVISOR.load(Ordering::Relaxed) == 2 && kvm::pv_eoi()
+}
+
+/// SINT_VECTOR handler body
+pub fn handle_interrupt() {
+    if HYPERVISOR.load(Ordering::Relaxed) == 1 {
+        vmbus::poll();
+    }
+}
+
+/// Zeroed page the hypervisor shares with this CPU for as long as the
+/// kernel runs. Heap memory is identity mapped, so the address is the
+/// guest-physical one the MSRs take.
+#[repr(C, align(4096))]
+struct SharedPage([u8; 4096]);
+
+fn shared_page() -> u64 {
+    Box::leak(Box::new(SharedPage([0; 4096]))) as *mut SharedPage as u64
+}
+
+fn current_cpu() -> usize {
+    crate::smp::current_cpu_id() as usize % crate::smp::MAX_CPUS
+}
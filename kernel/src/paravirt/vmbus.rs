@@ -0,0 +1,260 @@
+//! VMBus connection and channel enumeration
+//!
+//! The guest posts INITIATE_CONTACT to the host's message port, then asks
+//! for offers; the host answers with one OFFERCHANNEL message per synthetic
+//! device, delivered to the SynIC message slot of `MESSAGE_SINT`. Only the
+//! channel list is kept here; opening a channel's ring buffers is up to the
+//! driver that wants it.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use super::{hyperv, shared_page};
+use crate::sync::Mutex;
+
+/// SynIC interrupt source VMBus messages arrive on
+pub const MESSAGE_SINT: u32 = 2;
+
+// Port the host listens on, before and from protocol 5.0 on
+const MESSAGE_CONNECTION_ID: u32 = 1;
+const MESSAGE_CONNECTION_ID_4: u32 = 4;
+
+// HvPostMessage message type for VMBus
+const HVMSG_CHANNEL_MESSAGE: u32 = 1;
+
+// Protocol versions tried, newest first. Earlier versions want an
+// interrupt page rather than a SINT and are not supported.
+const VERSIONS: [u32; 4] = [(5 << 16) | 2, (5 << 16) | 1, 5 << 16, 4 << 16];
+
+const CHANNELMSG_OFFERCHANNEL: u32 = 1;
+const CHANNELMSG_RESCIND_CHANNELOFFER: u32 = 2;
+const CHANNELMSG_REQUESTOFFERS: u32 = 3;
+const CHANNELMSG_ALLOFFERS_DELIVERED: u32 = 4;
+const CHANNELMSG_INITIATE_CONTACT: u32 = 14;
+const CHANNELMSG_VERSION_RESPONSE: u32 = 15;
+
+// Host replies arrive within milliseconds; give up after a second
+const REPLY_TIMEOUT_100NS: u64 = 10_000_000;
+
+/// Interface or instance GUID, in its on-the-wire mixed-endian layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+// Well-known device classes, as (interface GUID text, name)
+const DEVICE_CLASSES: &[(&str, &str)] = &[
+    ("f8615163-df3e-46c5-913f-f2d2f965ed0e", "Network"),
+    ("ba6163d9-04a1-4d29-b605-72e2ffb1dc7f", "SCSI storage"),
+    ("32412632-86cb-44a2-9b5c-50d1417354f5", "IDE storage"),
+    ("f912ad6d-2b17-48ea-bd65-f927a61c7684", "Keyboard"),
+    ("cfa8b69e-5b4a-4cc0-b98b-8ba1a1f3f95a", "Mouse"),
+    ("da0a7802-e377-4aac-8e77-0558eb1073f8", "Synthetic video"),
+    ("57164f39-9115-4e78-ab55-382f3bd5422d", "Heartbeat"),
+    ("0e0b6031-5213-4934-818b-38d90ced39db", "Shutdown"),
+    ("9527e630-d0ae-497b-adce-e80ab0175caf", "Time sync"),
+    ("a9a0f4e7-5a45-4d96-b827-8a841e8c03e6", "Key-value exchange"),
+    ("35fa2e29-ea23-4236-96ae-3a6ebacba440", "Volume shadow copy"),
+    ("525074dc-8985-46e2-8057-a307dc18a502", "Dynamic memory"),
+    ("34d14be3-dee4-41c8-9ae7-6b174977c192", "File copy"),
+    ("44c4f61d-4444-4400-9d52-802e27ede19f", "PCI pass-through"),
+];
+
+/// A synthetic device the host offered
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub relid: u32,
+    pub interface: Guid,
+    /// Which device of its class this is
+    pub instance: Guid,
+}
+
+impl Channel {
+    /// Device class, if it is a well-known one
+    pub fn class_name(&self) -> Option<&'static str> {
+        let interface = format!("{}", self.interface);
+        DEVICE_CLASSES.iter().find(|(guid, _)| *guid == interface).map(|&(_, name)| name)
+    }
+
+    fn from_offer(payload: &[u8]) -> Self {
+        let mut interface = [0u8; 16];
+        let mut instance = [0u8; 16];
+        interface.copy_from_slice(&payload[8..24]);
+        instance.copy_from_slice(&payload[24..40]);
+
+        Channel {
+            relid: u32::from_le_bytes(payload[184..188].try_into().unwrap()),
+            interface: Guid(interface),
+            instance: Guid(instance),
+        }
+    }
+}
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+static VERSION: AtomicU32 = AtomicU32::new(0);
+static CHANNELS: Mutex<Vec<Channel>> = Mutex::new(Vec::new());
+
+fn post(connection_id: u32, message: &[u8]) -> Result<(), String> {
+    hyperv::post_message(connection_id, HVMSG_CHANNEL_MESSAGE, message)
+        .map_err(|status| format!("HvPostMessage failed with status {:#x}", status))
+}
+
+fn header(message_type: u32) -> Vec<u8> {
+    let mut message = Vec::with_capacity(48);
+    message.extend_from_slice(&message_type.to_le_bytes());
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message
+}
+
+/// Wait for the next channel message, as its type and payload
+fn receive() -> Option<(u32, [u8; hyperv::MESSAGE_PAYLOAD])> {
+    let deadline = hyperv::reference_time().map(|now| now + REPLY_TIMEOUT_100NS);
+    let mut spins = 0u64;
+    loop {
+        if let Some((_, payload)) = hyperv::take_message(MESSAGE_SINT) {
+            let message_type = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+            return Some((message_type, payload));
+        }
+        match (deadline, hyperv::reference_time()) {
+            (Some(deadline), Some(now)) if now >= deadline => return None,
+            (None, _) | (_, None) if spins > 100_000_000 => return None,
+            _ => {}
+        }
+        spins += 1;
+        core::hint::spin_loop();
+    }
+}
+
+/// Negotiate a protocol version with the host
+fn connect() -> Result<(u32, u32), String> {
+    // Monitor pages the host and guest use to signal channels without
+    // hypercalls; kept for as long as the connection is up
+    let monitor_pages = (shared_page(), shared_page());
+
+    for &version in VERSIONS.iter() {
+        let mut message = header(CHANNELMSG_INITIATE_CONTACT);
+        message.extend_from_slice(&version.to_le_bytes());
+        // Target vCPU for channel interrupts
+        message.extend_from_slice(&0u32.to_le_bytes());
+        // msg_sint, in place of the old interrupt page
+        message.extend_from_slice(&(MESSAGE_SINT as u64).to_le_bytes());
+        message.extend_from_slice(&monitor_pages.0.to_le_bytes());
+        message.extend_from_slice(&monitor_pages.1.to_le_bytes());
+
+        let connection_id = if version >= 5 << 16 { MESSAGE_CONNECTION_ID_4 } else { MESSAGE_CONNECTION_ID };
+        post(connection_id, &message)?;
+
+        loop {
+            let (message_type, payload) = receive().ok_or_else(|| String::from("no reply from host"))?;
+            if message_type != CHANNELMSG_VERSION_RESPONSE {
+                continue;
+            }
+            if payload[8] == 0 {
+                break;
+            }
+            // From 5.0 on the host picks the port for later messages
+            let connection_id = if version >= 5 << 16 {
+                u32::from_le_bytes(payload[12..16].try_into().unwrap())
+            } else {
+                MESSAGE_CONNECTION_ID
+            };
+            return Ok((version, connection_id));
+        }
+    }
+    Err(String::from("host supports no VMBus version this kernel speaks"))
+}
+
+/// Connect to the host and collect its channel offers
+pub fn init() -> Result<(), String> {
+    let status = hyperv::status();
+    if !status.hypercalls || !status.synic {
+        return Err(String::from("needs hypercalls and SynIC"));
+    }
+
+    let (version, connection_id) = connect()?;
+    post(connection_id, &header(CHANNELMSG_REQUESTOFFERS))?;
+
+    let mut channels = Vec::new();
+    loop {
+        let (message_type, payload) = receive().ok_or_else(|| String::from("offers stopped arriving"))?;
+        match message_type {
+            CHANNELMSG_OFFERCHANNEL => channels.push(Channel::from_offer(&payload)),
+            CHANNELMSG_ALLOFFERS_DELIVERED => break,
+            _ => {}
+        }
+    }
+
+    crate::serial_println!(
+        "[PARAVIRT] VMBus {}.{}: {} channels",
+        version >> 16,
+        version & 0xffff,
+        channels.len()
+    );
+    for channel in &channels {
+        crate::serial_println!(
+            "[PARAVIRT]   relid {} {} ({})",
+            channel.relid,
+            channel.interface,
+            channel.class_name().unwrap_or("unknown")
+        );
+    }
+
+    *CHANNELS.lock() = channels;
+    VERSION.store(version, Ordering::Relaxed);
+    CONNECTED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Apply offers and rescinds the host sends after enumeration. Runs in
+/// the SINT interrupt; a message is left in its slot if the list is busy
+/// and picked up with the next one.
+pub fn poll() {
+    if !CONNECTED.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(mut channels) = CHANNELS.try_lock() else {
+        return;
+    };
+    while let Some((_, payload)) = hyperv::take_message(MESSAGE_SINT) {
+        let message_type = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+        match message_type {
+            CHANNELMSG_OFFERCHANNEL => channels.push(Channel::from_offer(&payload)),
+            CHANNELMSG_RESCIND_CHANNELOFFER => {
+                let relid = u32::from_le_bytes(payload[8..12].try_into().unwrap());
+                channels.retain(|channel| channel.relid != relid);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Negotiated protocol version, once connected
+pub fn version() -> Option<u32> {
+    CONNECTED.load(Ordering::Acquire).then(|| VERSION.load(Ordering::Relaxed))
+}
+
+pub fn channels() -> Vec<Channel> {
+    CHANNELS.lock().clone()
+}
@@ -0,0 +1,381 @@
+//! PCIe Advanced Error Reporting
+//!
+//! The error status of every function with an AER capability is polled
+//! from the main loop. Correctable errors, which the link recovered from by
+//! itself, are logged at warning level; uncorrectable ones are logged as
+//! errors along with the header of the TLP that caused them. Either way the
+//! status is cleared so the next error shows. Functions whose AER registers
+//! cannot be reached, without MMCONFIG, still report the summary bits in
+//! their PCIe device status.
+//!
+//! With `pci.aer_reset` on, a fatal error resets the secondary bus of the
+//! bridge above the function: drivers are unbound from everything on it,
+//! the bus is reset, the functions' configuration is restored, and the
+//! devices are probed again.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{
+    PciLocation, PCIE_CONTROLLER, PCI_BRIDGE_CONTROL, PCI_BRIDGE_CTL_BUS_RESET, PCI_CAP_ID_EXP, PCI_COMMAND,
+    PCI_EXT_CAP_ID_ERR, PCI_HEADER_TYPE_BRIDGE, PCI_SECONDARY_BUS, PCI_SUBORDINATE_BUS, PCI_VENDOR_ID,
+};
+use crate::boot::cmdline::Param;
+use crate::driver::bus::{find_pci_device, pci_name};
+use crate::driver::probe::{probe_device, unbind};
+use crate::{pr_err, pr_info, pr_warn};
+
+static AER_RESET: Param<bool> =
+    Param::new("pci.aer_reset", false, "Reset the bus below a function that reports a fatal PCIe error");
+
+// AER capability registers, from the start of the capability
+const PCI_ERR_UNCOR_STATUS: u16 = 0x04;
+const PCI_ERR_UNCOR_SEVER: u16 = 0x0C;
+const PCI_ERR_COR_STATUS: u16 = 0x10;
+const PCI_ERR_CAP: u16 = 0x18;
+const PCI_ERR_HEADER_LOG: u16 = 0x1C;
+const PCI_ERR_ROOT_STATUS: u16 = 0x30;
+const PCI_ERR_ROOT_ERR_SRC: u16 = 0x34;
+
+// The first error pointer in PCI_ERR_CAP
+const PCI_ERR_CAP_FEP_MASK: u32 = 0x1F;
+
+// PCIe capability registers
+const PCI_EXP_FLAGS: u8 = 0x02;
+const PCI_EXP_DEVSTA: u8 = 0x0A;
+const FLAGS_TYPE_ROOT_PORT: u16 = 0x4;
+
+// Device status bits, write-one-to-clear
+const DEVSTA_CED: u16 = 1 << 0;
+const DEVSTA_NFED: u16 = 1 << 1;
+const DEVSTA_FED: u16 = 1 << 2;
+const DEVSTA_URD: u16 = 1 << 3;
+const DEVSTA_ERRORS: u16 = DEVSTA_CED | DEVSTA_NFED | DEVSTA_FED | DEVSTA_URD;
+
+const UNCORRECTABLE: &[(u32, &str)] = &[
+    (1 << 4, "Data Link Protocol"),
+    (1 << 5, "Surprise Down"),
+    (1 << 12, "Poisoned TLP"),
+    (1 << 13, "Flow Control Protocol"),
+    (1 << 14, "Completion Timeout"),
+    (1 << 15, "Completer Abort"),
+    (1 << 16, "Unexpected Completion"),
+    (1 << 17, "Receiver Overflow"),
+    (1 << 18, "Malformed TLP"),
+    (1 << 19, "ECRC"),
+    (1 << 20, "Unsupported Request"),
+    (1 << 21, "ACS Violation"),
+    (1 << 22, "Uncorrectable Internal"),
+    (1 << 23, "MC Blocked TLP"),
+    (1 << 24, "AtomicOp Egress Blocked"),
+    (1 << 25, "TLP Prefix Blocked"),
+];
+
+const CORRECTABLE: &[(u32, &str)] = &[
+    (1 << 0, "Receiver"),
+    (1 << 6, "Bad TLP"),
+    (1 << 7, "Bad DLLP"),
+    (1 << 8, "Replay Num Rollover"),
+    (1 << 12, "Replay Timer Timeout"),
+    (1 << 13, "Advisory Non-Fatal"),
+    (1 << 14, "Corrected Internal"),
+    (1 << 15, "Header Log Overflow"),
+];
+
+// Reset timing: how long the reset is held, how long functions get before
+// they are read, and how long they may take to answer after that
+const RESET_HOLD_NS: u64 = 2_000_000;
+const RESET_SETTLE_NS: u64 = 100_000_000;
+const RESET_READY_NS: u64 = 1_000_000_000;
+
+/// Errors a function has reported since boot
+#[derive(Debug, Clone, Copy)]
+pub struct Counters {
+    pub location: PciLocation,
+    pub correctable: u64,
+    pub nonfatal: u64,
+    pub fatal: u64,
+    /// Bus resets this function's errors caused
+    pub resets: u64,
+}
+
+static COUNTERS: Mutex<Vec<Counters>> = Mutex::new(Vec::new());
+
+fn count(location: PciLocation, f: impl FnOnce(&mut Counters)) {
+    let mut counters = COUNTERS.lock();
+    let index = match counters.iter().position(|c| c.location == location) {
+        Some(index) => index,
+        None => {
+            counters.push(Counters { location, correctable: 0, nonfatal: 0, fatal: 0, resets: 0 });
+            counters.len() - 1
+        }
+    };
+    f(&mut counters[index]);
+}
+
+/// Every function that has reported an error, for the shell
+pub fn counters() -> Vec<Counters> {
+    COUNTERS.lock().clone()
+}
+
+fn names(status: u32, table: &[(u32, &str)]) -> String {
+    let names: Vec<&str> = table.iter().filter(|(bit, _)| status & bit != 0).map(|(_, name)| *name).collect();
+    if names.is_empty() {
+        String::from("Unknown")
+    } else {
+        names.join(", ")
+    }
+}
+
+// Where a function's error registers are
+#[derive(Clone, Copy)]
+struct Reporter {
+    location: PciLocation,
+    pcie: Option<u8>,
+    aer: Option<u16>,
+    root_port: bool,
+}
+
+fn reporters() -> Vec<Reporter> {
+    let controller = PCIE_CONTROLLER.lock();
+    let aer_reachable = controller.has_extended_config();
+    controller
+        .devices()
+        .iter()
+        .filter_map(|device| {
+            let pcie = device.get_capability(PCI_CAP_ID_EXP).map(|cap| cap.offset);
+            let aer =
+                device.get_extended_capability(PCI_EXT_CAP_ID_ERR).map(|cap| cap.offset).filter(|_| aer_reachable);
+            let root_port = pcie.is_some_and(|cap| {
+                (controller.read16(device.location, cap + PCI_EXP_FLAGS) >> 4) & 0xF == FLAGS_TYPE_ROOT_PORT
+            });
+            (pcie.is_some() || aer.is_some()).then_some(Reporter { location: device.location, pcie, aer, root_port })
+        })
+        .collect()
+}
+
+/// Report which functions can report errors
+pub fn init() {
+    let reporters = reporters();
+    let with_aer = reporters.iter().filter(|r| r.aer.is_some()).count();
+    if !PCIE_CONTROLLER.lock().has_extended_config() {
+        pr_info!("AER: no extended configuration access; {} functions report device status only", reporters.len());
+    } else {
+        pr_info!("AER: {} of {} PCIe functions have AER", with_aer, reporters.len());
+    }
+    // Reads the option now, so it is listed among those in force
+    AER_RESET.get();
+}
+
+// Log and clear one function's errors; returns whether any was fatal
+fn check(reporter: &Reporter) -> bool {
+    let name = pci_name(&reporter.location);
+    let controller = PCIE_CONTROLLER.lock();
+    let mut fatal = false;
+
+    if let Some(aer) = reporter.aer {
+        let read = |register: u16| controller.read_ext32(reporter.location, aer + register);
+        let write = |register: u16, value: u32| controller.write_ext32(reporter.location, aer + register, value);
+
+        let cor = read(PCI_ERR_COR_STATUS);
+        if cor != 0 && cor != 0xFFFFFFFF {
+            pr_warn!("AER: {} corrected error: {} (status {:#010x})", name, names(cor, CORRECTABLE), cor);
+            write(PCI_ERR_COR_STATUS, cor);
+            count(reporter.location, |c| c.correctable += 1);
+        }
+
+        let uncor = read(PCI_ERR_UNCOR_STATUS);
+        if uncor != 0 && uncor != 0xFFFFFFFF {
+            let severe = uncor & read(PCI_ERR_UNCOR_SEVER) != 0;
+            pr_err!(
+                "AER: {} uncorrected ({}) error: {} (status {:#010x})",
+                name,
+                if severe { "fatal" } else { "non-fatal" },
+                names(uncor, UNCORRECTABLE),
+                uncor
+            );
+            let first = read(PCI_ERR_CAP) & PCI_ERR_CAP_FEP_MASK;
+            let header: Vec<u32> = (0..4).map(|i| read(PCI_ERR_HEADER_LOG + i * 4)).collect();
+            if header.iter().any(|&word| word != 0) {
+                pr_err!(
+                    "AER: {} first error bit {}, TLP header: {:08x} {:08x} {:08x} {:08x}",
+                    name,
+                    first,
+                    header[0],
+                    header[1],
+                    header[2],
+                    header[3]
+                );
+            }
+            write(PCI_ERR_UNCOR_STATUS, uncor);
+            count(reporter.location, |c| if severe { c.fatal += 1 } else { c.nonfatal += 1 });
+            fatal |= severe;
+        }
+
+        if reporter.root_port {
+            // Error messages the port collected from below; the functions
+            // that sent them are checked on their own
+            let root = read(PCI_ERR_ROOT_STATUS);
+            if root != 0 && root != 0xFFFFFFFF {
+                let source = read(PCI_ERR_ROOT_ERR_SRC);
+                pr_info!(
+                    "AER: {} root status {:#x}, last correctable from {:04x}, uncorrectable from {:04x}",
+                    name,
+                    root,
+                    source & 0xFFFF,
+                    source >> 16
+                );
+                write(PCI_ERR_ROOT_STATUS, root);
+            }
+        }
+    }
+
+    if let Some(pcie) = reporter.pcie {
+        let status = controller.read16(reporter.location, pcie + PCI_EXP_DEVSTA);
+        let errors = status & DEVSTA_ERRORS;
+        if errors != 0 && status != 0xFFFF {
+            // Already logged in detail when AER is there
+            if reporter.aer.is_none() {
+                if errors & DEVSTA_FED != 0 {
+                    pr_err!("AER: {} fatal error detected", name);
+                    count(reporter.location, |c| c.fatal += 1);
+                    fatal = true;
+                } else if errors & (DEVSTA_NFED | DEVSTA_URD) != 0 {
+                    pr_err!("AER: {} non-fatal error detected (device status {:#06x})", name, status);
+                    count(reporter.location, |c| c.nonfatal += 1);
+                } else {
+                    pr_warn!("AER: {} correctable error detected", name);
+                    count(reporter.location, |c| c.correctable += 1);
+                }
+            }
+            controller.write16(reporter.location, pcie + PCI_EXP_DEVSTA, errors);
+        }
+    }
+
+    fatal
+}
+
+// Clear a function's errors without logging them
+fn clear(reporter: &Reporter) {
+    let controller = PCIE_CONTROLLER.lock();
+    if let Some(aer) = reporter.aer {
+        // Only root ports have the root status register
+        let registers: &[u16] = if reporter.root_port {
+            &[PCI_ERR_COR_STATUS, PCI_ERR_UNCOR_STATUS, PCI_ERR_ROOT_STATUS]
+        } else {
+            &[PCI_ERR_COR_STATUS, PCI_ERR_UNCOR_STATUS]
+        };
+        for &register in registers {
+            let status = controller.read_ext32(reporter.location, aer + register);
+            controller.write_ext32(reporter.location, aer + register, status);
+        }
+    }
+    if let Some(pcie) = reporter.pcie {
+        controller.write16(reporter.location, pcie + PCI_EXP_DEVSTA, DEVSTA_ERRORS);
+    }
+}
+
+/// Log and clear the errors functions have reported; called from the main
+/// loop
+pub fn poll() {
+    for reporter in reporters() {
+        if check(&reporter) && AER_RESET.get() {
+            if let Err(e) = recover(reporter.location) {
+                pr_err!("AER: {} not recovered: {}", pci_name(&reporter.location), e);
+            }
+        }
+    }
+}
+
+// The bridge whose secondary bus a function is on
+fn upstream_bridge(location: PciLocation) -> Option<PciLocation> {
+    let controller = PCIE_CONTROLLER.lock();
+    controller
+        .devices()
+        .iter()
+        .filter(|device| device.header_type == PCI_HEADER_TYPE_BRIDGE)
+        .map(|device| device.location)
+        .find(|bridge| {
+            bridge.segment == location.segment && controller.read8(*bridge, PCI_SECONDARY_BUS) == location.bus
+        })
+}
+
+/// Reset the secondary bus of the bridge above a function, and bring the
+/// functions on it back
+pub fn recover(location: PciLocation) -> Result<(), &'static str> {
+    use crate::time::hrtimer::sleep_ns;
+
+    let bridge = upstream_bridge(location).ok_or("no bridge above it to reset")?;
+    let (secondary, subordinate) = {
+        let controller = PCIE_CONTROLLER.lock();
+        (controller.read8(bridge, PCI_SECONDARY_BUS), controller.read8(bridge, PCI_SUBORDINATE_BUS))
+    };
+    let functions: Vec<PciLocation> = PCIE_CONTROLLER
+        .lock()
+        .devices()
+        .iter()
+        .map(|device| device.location)
+        .filter(|f| f.segment == bridge.segment && (secondary..=subordinate).contains(&f.bus))
+        .collect();
+    pr_warn!("AER: resetting bus {:02x} below {}", secondary, pci_name(&bridge));
+
+    // Devices behind a bridge come after it, so unbinding in reverse takes
+    // children first
+    for function in functions.iter().rev() {
+        if let Some(id) = find_pci_device(*function) {
+            unbind(id);
+        }
+    }
+
+    // The header past the IDs and class, which the reset clears: BARs, bus
+    // numbers and windows, the ROM, and the interrupt line
+    let saved: Vec<(PciLocation, u16, Vec<u32>)> = {
+        let controller = PCIE_CONTROLLER.lock();
+        functions
+            .iter()
+            .map(|&f| {
+                let command = controller.read16(f, PCI_COMMAND);
+                let header = (0x0C..0x40).step_by(4).map(|offset| controller.read32(f, offset)).collect();
+                (f, command, header)
+            })
+            .collect()
+    };
+
+    let control = PCIE_CONTROLLER.lock().read16(bridge, PCI_BRIDGE_CONTROL);
+    PCIE_CONTROLLER.lock().write16(bridge, PCI_BRIDGE_CONTROL, control | PCI_BRIDGE_CTL_BUS_RESET);
+    sleep_ns(RESET_HOLD_NS);
+    PCIE_CONTROLLER.lock().write16(bridge, PCI_BRIDGE_CONTROL, control & !PCI_BRIDGE_CTL_BUS_RESET);
+    sleep_ns(RESET_SETTLE_NS);
+
+    // Bridges are restored before what is behind them, so their bus
+    // numbers route configuration requests again
+    let deadline = crate::time::clocksource::now_ns() + RESET_READY_NS;
+    for (f, command, header) in &saved {
+        while PCIE_CONTROLLER.lock().read16(*f, PCI_VENDOR_ID) == 0xFFFF {
+            if crate::time::clocksource::now_ns() >= deadline {
+                return Err("function did not come back after reset");
+            }
+            sleep_ns(RESET_HOLD_NS);
+        }
+        let controller = PCIE_CONTROLLER.lock();
+        for (offset, value) in (0x0C..0x40u8).step_by(4).zip(header) {
+            controller.write32(*f, offset, *value);
+        }
+        controller.write16(*f, PCI_COMMAND, *command);
+    }
+    count(location, |c| c.resets += 1);
+
+    // Whatever the reset left latched is from before it, or the reset itself
+    for reporter in reporters().iter().filter(|r| functions.contains(&r.location) || r.location == bridge) {
+        clear(reporter);
+    }
+    for function in &functions {
+        if let Some(id) = find_pci_device(*function) {
+            probe_device(id);
+        }
+    }
+    pr_info!("AER: bus {:02x} below {} recovered", secondary, pci_name(&bridge));
+    Ok(())
+}
@@ -0,0 +1,442 @@
+//! Native PCIe hot-plug
+//!
+//! Root and downstream ports whose PCIe capability reports a hot-plug
+//! capable slot are found at boot. Interrupts are off, so each slot's status
+//! register is polled from the main loop instead of taking the hot-plug
+//! interrupt. A card arriving is powered, its functions are scanned once the
+//! link is up and added to the device tree, where drivers bind them. A card
+//! leaving has its functions taken out of the tree, drivers first.
+//!
+//! The attention button works as the PCIe spec has it: a press starts five
+//! seconds with the power indicator blinking, a second press in that time
+//! cancels, and otherwise the slot is powered down if it was up and up if it
+//! was down. Powering down that way asks the drivers first, and any of them
+//! can keep the slot on; a card pulled out, a power fault or an opened latch
+//! is a surprise removal, which they only hear of.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{PciLocation, PCIE_CONTROLLER, PCI_CAP_ID_EXP, PCI_SECONDARY_BUS, PCI_SUBORDINATE_BUS};
+use crate::driver::bus::{add_pci_device, find_pci_device, pci_name};
+use crate::driver::DeviceId;
+use crate::driver::pnp;
+use crate::{pr_info, pr_warn};
+
+// PCIe capability registers, from the start of the capability
+const PCI_EXP_FLAGS: u8 = 0x02;
+const PCI_EXP_LNKCAP: u8 = 0x0C;
+const PCI_EXP_LNKSTA: u8 = 0x12;
+const PCI_EXP_SLTCAP: u8 = 0x14;
+const PCI_EXP_SLTCTL: u8 = 0x18;
+const PCI_EXP_SLTSTA: u8 = 0x1A;
+
+const FLAGS_SLOT: u16 = 1 << 8;
+const FLAGS_TYPE_ROOT_PORT: u16 = 0x4;
+const FLAGS_TYPE_DOWNSTREAM: u16 = 0x6;
+
+const LNKCAP_DLLLARC: u32 = 1 << 20;
+const LNKSTA_DLLLA: u16 = 1 << 13;
+
+const SLTCAP_ABP: u32 = 1 << 0;
+const SLTCAP_PCP: u32 = 1 << 1;
+const SLTCAP_AIP: u32 = 1 << 3;
+const SLTCAP_PIP: u32 = 1 << 4;
+const SLTCAP_HPC: u32 = 1 << 6;
+const SLTCAP_PSN_SHIFT: u32 = 19;
+
+const SLTCTL_ATTN_IND_MASK: u16 = 3 << 6;
+const SLTCTL_PWR_IND_MASK: u16 = 3 << 8;
+const SLTCTL_PCC: u16 = 1 << 10;
+
+// Indicator settings, for both the attention and the power indicator
+const IND_ON: u16 = 1;
+const IND_BLINK: u16 = 2;
+const IND_OFF: u16 = 3;
+
+const SLTSTA_ABP: u16 = 1 << 0;
+const SLTSTA_PFD: u16 = 1 << 1;
+const SLTSTA_MRLSC: u16 = 1 << 2;
+const SLTSTA_PDC: u16 = 1 << 3;
+const SLTSTA_CC: u16 = 1 << 4;
+const SLTSTA_MRLSS: u16 = 1 << 5;
+const SLTSTA_PDS: u16 = 1 << 6;
+const SLTSTA_DLLSC: u16 = 1 << 8;
+// The write-one-to-clear event bits
+const SLTSTA_EVENTS: u16 = SLTSTA_ABP | SLTSTA_PFD | SLTSTA_MRLSC | SLTSTA_PDC | SLTSTA_CC | SLTSTA_DLLSC;
+
+// How long a button press waits before acting, during which a second press
+// cancels it
+const BUTTON_WAIT_NS: u64 = 5_000_000_000;
+// After power-on, the time the link gets to come up, and the time a card
+// gets after it has before its configuration space is read
+const LINK_TIMEOUT_NS: u64 = 1_000_000_000;
+const SETTLE_NS: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotState {
+    /// Nothing in the slot
+    Empty,
+    /// A card that is not powered, or was turned off
+    Off,
+    /// Powered, waiting for the link to come up
+    PoweringOn { since: u64 },
+    /// The card's functions are in the device tree
+    On,
+    /// The attention button was pressed; at the deadline the slot is
+    /// turned off if it was on, and on if it was not
+    Pending { deadline: u64, was_on: bool },
+}
+
+impl SlotState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SlotState::Empty => "empty",
+            SlotState::Off => "off",
+            SlotState::PoweringOn { .. } => "powering on",
+            SlotState::On => "on",
+            SlotState::Pending { .. } => "button pressed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Slot {
+    /// The port the slot is below
+    pub port: PciLocation,
+    /// The physical slot number firmware gave it
+    pub number: u32,
+    pub state: SlotState,
+    cap: u8,
+    slot_caps: u32,
+    link_caps: u32,
+}
+
+impl Slot {
+    fn read16(&self, register: u8) -> u16 {
+        PCIE_CONTROLLER.lock().read16(self.port, self.cap + register)
+    }
+
+    fn write16(&self, register: u8, value: u16) {
+        PCIE_CONTROLLER.lock().write16(self.port, self.cap + register, value)
+    }
+
+    fn has(&self, cap: u32) -> bool {
+        self.slot_caps & cap != 0
+    }
+
+    /// The buses behind the port, where the card's functions are
+    pub fn buses(&self) -> (u8, u8) {
+        let controller = PCIE_CONTROLLER.lock();
+        (controller.read8(self.port, PCI_SECONDARY_BUS), controller.read8(self.port, PCI_SUBORDINATE_BUS))
+    }
+
+    pub fn present(&self) -> bool {
+        self.read16(PCI_EXP_SLTSTA) & SLTSTA_PDS != 0
+    }
+
+    fn link_active(&self) -> bool {
+        self.read16(PCI_EXP_LNKSTA) & LNKSTA_DLLLA != 0
+    }
+
+    // Slot control writes are commands the port completes in its own time;
+    // the completion is not waited for, as nothing depends on it
+    fn control(&self, mask: u16, value: u16) {
+        let ctl = self.read16(PCI_EXP_SLTCTL);
+        self.write16(PCI_EXP_SLTCTL, (ctl & !mask) | value);
+    }
+
+    fn power_indicator(&self, setting: u16) {
+        if self.has(SLTCAP_PIP) {
+            self.control(SLTCTL_PWR_IND_MASK, setting << 8);
+        }
+    }
+
+    fn attention_indicator(&self, setting: u16) {
+        if self.has(SLTCAP_AIP) {
+            self.control(SLTCTL_ATTN_IND_MASK, setting << 6);
+        }
+    }
+
+    fn set_power(&self, on: bool) {
+        if self.has(SLTCAP_PCP) {
+            self.control(SLTCTL_PCC, if on { 0 } else { SLTCTL_PCC });
+        }
+        self.power_indicator(if on { IND_ON } else { IND_OFF });
+    }
+
+    fn power_on(&mut self, now: u64) {
+        self.set_power(true);
+        self.attention_indicator(IND_OFF);
+        self.state = SlotState::PoweringOn { since: now };
+    }
+
+    // Whether the card's functions are in the device tree
+    fn populated(&self) -> bool {
+        matches!(self.state, SlotState::On | SlotState::Pending { was_on: true, .. })
+    }
+
+    // Power down a card that is gone, or has to go
+    fn power_off(&mut self) {
+        if self.populated() {
+            let _ = self.remove_functions(true);
+        }
+        self.set_power(false);
+        self.state = if self.present() { SlotState::Off } else { SlotState::Empty };
+    }
+
+    // Power down on request, if the card's drivers agree
+    fn request_power_off(&mut self) -> Result<(), String> {
+        if self.populated() {
+            self.remove_functions(false)?;
+        }
+        self.set_power(false);
+        self.state = if self.present() { SlotState::Off } else { SlotState::Empty };
+        Ok(())
+    }
+
+    // Whether the card is ready for its functions to be scanned, or None if
+    // it never will be
+    fn ready(&self, since: u64, now: u64) -> Option<bool> {
+        let elapsed = now.saturating_sub(since);
+        if self.link_caps & LNKCAP_DLLLARC == 0 {
+            // No way to tell when the link is up, so wait as long as it may take
+            return Some(elapsed >= LINK_TIMEOUT_NS);
+        }
+        if self.link_active() {
+            Some(elapsed >= SETTLE_NS)
+        } else if elapsed >= LINK_TIMEOUT_NS {
+            None
+        } else {
+            Some(false)
+        }
+    }
+
+    fn add_functions(&self) {
+        let (secondary, _) = self.buses();
+        let found = PCIE_CONTROLLER.lock().rescan_bus(self.port.segment, secondary);
+        match found {
+            Ok(found) => {
+                pr_info!("pciehp: slot {}: {} functions found", self.number, found.len());
+                // Bridges come before the devices behind them
+                for device in &found {
+                    if let Err(e) = add_pci_device(device) {
+                        pr_warn!("pciehp: cannot add {}: {}", pci_name(&device.location), e);
+                    }
+                }
+            }
+            Err(e) => pr_warn!("pciehp: slot {}: bus scan failed: {}", self.number, e),
+        }
+    }
+
+    fn remove_functions(&self, surprise: bool) -> Result<(), String> {
+        let (secondary, subordinate) = self.buses();
+        let segment = self.port.segment;
+        let locations: Vec<PciLocation> = PCIE_CONTROLLER
+            .lock()
+            .devices()
+            .iter()
+            .map(|device| device.location)
+            .filter(|location| location.segment == segment && (secondary..=subordinate).contains(&location.bus))
+            .collect();
+        // Out of the tree first, so drivers can still reach the functions
+        // while the card is there; devices behind a bridge go with it
+        let ids: Vec<DeviceId> = locations.iter().filter_map(|location| find_pci_device(*location)).collect();
+        if surprise {
+            for id in ids {
+                pnp::surprise_remove(id);
+            }
+        } else {
+            pnp::query_remove(&ids)?;
+        }
+        PCIE_CONTROLLER.lock().remove_buses(segment, secondary..=subordinate);
+        pr_info!("pciehp: slot {}: {} functions removed", self.number, locations.len());
+        Ok(())
+    }
+
+    fn button_pressed(&mut self, now: u64) {
+        match self.state {
+            SlotState::Pending { was_on, .. } => {
+                pr_info!("pciehp: slot {}: button press cancelled", self.number);
+                self.power_indicator(if was_on { IND_ON } else { IND_OFF });
+                self.state = if was_on { SlotState::On } else { SlotState::Off };
+            }
+            SlotState::On | SlotState::Off => {
+                let was_on = self.state == SlotState::On;
+                pr_info!(
+                    "pciehp: slot {}: button pressed, powering {} in 5 s",
+                    self.number,
+                    if was_on { "off" } else { "on" }
+                );
+                self.power_indicator(IND_BLINK);
+                self.state = SlotState::Pending { deadline: now + BUTTON_WAIT_NS, was_on };
+            }
+            // Ignored while the slot is empty or powering on
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self, now: u64) {
+        let status = self.read16(PCI_EXP_SLTSTA);
+        let events = status & SLTSTA_EVENTS;
+        if events != 0 {
+            self.write16(PCI_EXP_SLTSTA, events);
+        }
+
+        if events & SLTSTA_PFD != 0 && self.state != SlotState::Empty {
+            pr_warn!("pciehp: slot {}: power fault", self.number);
+            self.power_off();
+            self.attention_indicator(IND_ON);
+        }
+        if events & SLTSTA_MRLSC != 0 && status & SLTSTA_MRLSS != 0 && self.state != SlotState::Empty {
+            pr_info!("pciehp: slot {}: latch opened", self.number);
+            self.power_off();
+        }
+        if events & (SLTSTA_PDC | SLTSTA_DLLSC) != 0 {
+            let present = status & SLTSTA_PDS != 0;
+            match (present, self.state) {
+                (true, SlotState::Empty) => {
+                    pr_info!("pciehp: slot {}: card present", self.number);
+                    self.power_on(now);
+                }
+                (false, SlotState::Empty) => {}
+                (false, _) => {
+                    pr_info!("pciehp: slot {}: card removed", self.number);
+                    self.power_off();
+                }
+                _ => {}
+            }
+        }
+        if events & SLTSTA_ABP != 0 {
+            self.button_pressed(now);
+        }
+
+        match self.state {
+            SlotState::PoweringOn { since } => match self.ready(since, now) {
+                Some(true) => {
+                    self.add_functions();
+                    self.state = SlotState::On;
+                }
+                Some(false) => {}
+                None => {
+                    pr_warn!("pciehp: slot {}: link did not come up", self.number);
+                    self.set_power(false);
+                    self.attention_indicator(IND_ON);
+                    self.state = SlotState::Off;
+                }
+            },
+            SlotState::Pending { deadline, was_on } if now >= deadline => {
+                if was_on {
+                    if let Err(e) = self.request_power_off() {
+                        pr_warn!("pciehp: slot {}: staying on: {}", self.number, e);
+                        self.power_indicator(IND_ON);
+                        self.state = SlotState::On;
+                    }
+                } else {
+                    self.power_on(now);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+static SLOTS: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+
+/// Find the hot-plug slots below the ports `pcie` enumerated; the device
+/// tree has to be there already
+pub fn init() {
+    let now = crate::time::clocksource::now_ns();
+    let ports: Vec<(PciLocation, u8)> = PCIE_CONTROLLER
+        .lock()
+        .devices()
+        .iter()
+        .filter_map(|device| Some((device.location, device.get_capability(PCI_CAP_ID_EXP)?.offset)))
+        .collect();
+
+    let mut slots = Vec::new();
+    for (port, cap) in ports {
+        let (flags, slot_caps, link_caps) = {
+            let controller = PCIE_CONTROLLER.lock();
+            (
+                controller.read16(port, cap + PCI_EXP_FLAGS),
+                controller.read32(port, cap + PCI_EXP_SLTCAP),
+                controller.read32(port, cap + PCI_EXP_LNKCAP),
+            )
+        };
+        let port_type = (flags >> 4) & 0xF;
+        if flags & FLAGS_SLOT == 0
+            || !matches!(port_type, FLAGS_TYPE_ROOT_PORT | FLAGS_TYPE_DOWNSTREAM)
+            || slot_caps & SLTCAP_HPC == 0
+        {
+            continue;
+        }
+        let mut slot =
+            Slot { port, number: slot_caps >> SLTCAP_PSN_SHIFT, state: SlotState::Empty, cap, slot_caps, link_caps };
+        // Events from before boot are stale; what is in the slot now counts
+        slot.write16(PCI_EXP_SLTSTA, SLTSTA_EVENTS);
+        if slot.present() {
+            let (secondary, _) = slot.buses();
+            let populated = PCIE_CONTROLLER.lock().devices().iter().any(|device| device.location.bus == secondary);
+            if populated {
+                slot.state = SlotState::On;
+                slot.power_indicator(IND_ON);
+            } else {
+                slot.power_on(now);
+            }
+        }
+        pr_info!(
+            "pciehp: slot {} at {}{}{}: {}",
+            slot.number,
+            pci_name(&port),
+            if slot.has(SLTCAP_ABP) { ", attention button" } else { "" },
+            if slot.has(SLTCAP_PCP) { ", power control" } else { "" },
+            slot.state.name()
+        );
+        slots.push(slot);
+    }
+    *SLOTS.lock() = slots;
+}
+
+/// Act on slot events; called from the main loop
+pub fn poll(now: u64) {
+    for slot in SLOTS.lock().iter_mut() {
+        slot.poll(now);
+    }
+}
+
+/// Every hot-plug slot, for the shell
+pub fn slots() -> Vec<Slot> {
+    SLOTS.lock().clone()
+}
+
+/// Turn a slot on or off as its attention button would, without the wait
+pub fn set_slot_power(number: u32, on: bool) -> Result<(), String> {
+    let now = crate::time::clocksource::now_ns();
+    let mut slots = SLOTS.lock();
+    let slot = slots.iter_mut().find(|slot| slot.number == number).ok_or("no such slot")?;
+    match (on, slot.state) {
+        (_, SlotState::Empty) => Err(String::from("slot is empty")),
+        (true, SlotState::Off | SlotState::Pending { was_on: false, .. }) => {
+            slot.power_on(now);
+            Ok(())
+        }
+        (false, SlotState::On) => slot.request_power_off(),
+        (false, SlotState::Pending { was_on: true, .. }) => {
+            let result = slot.request_power_off();
+            if result.is_err() {
+                slot.power_indicator(IND_ON);
+                slot.state = SlotState::On;
+            }
+            result
+        }
+        (false, SlotState::PoweringOn { .. }) => {
+            slot.set_power(false);
+            slot.state = SlotState::Off;
+            Ok(())
+        }
+        _ => Err(String::from(if on { "slot is already on" } else { "slot is already off" })),
+    }
+}
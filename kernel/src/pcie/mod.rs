@@ -4,6 +4,8 @@ pub mod device;
 pub mod bus;
 pub mod capability;
 pub mod msi;
+pub mod hotplug;
+pub mod aer;
 
 use alloc::vec::Vec;
 use alloc::vec;
@@ -44,6 +46,16 @@ pub const PCI_INTERRUPT_PIN: u8 = 0x3D;
 pub const PCI_MIN_GRANT: u8 = 0x3E;
 pub const PCI_MAX_LATENCY: u8 = 0x3F;
 
+// Type 1 (bridge) header registers
+pub const PCI_PRIMARY_BUS: u8 = 0x18;
+pub const PCI_SECONDARY_BUS: u8 = 0x19;
+pub const PCI_SUBORDINATE_BUS: u8 = 0x1A;
+pub const PCI_BRIDGE_CONTROL: u8 = 0x3E;
+
+// Bridge Control Register Bits
+pub const PCI_BRIDGE_CTL_SERR: u16 = 1 << 1;
+pub const PCI_BRIDGE_CTL_BUS_RESET: u16 = 1 << 6;
+
 // PCI Command Register Bits
 pub const PCI_COMMAND_IO: u16 = 1 << 0;
 pub const PCI_COMMAND_MEMORY: u16 = 1 << 1;
@@ -257,9 +269,8 @@ impl PcieController {
     }
     
     fn detect_mmconfig(&self) -> Option<u64> {
-        // This would read the ACPI MCFG table
-        // For now, return None to use legacy access
-        None
+        // Segment 0 from the ACPI MCFG table, if ACPI has run
+        crate::acpi::pci::segment_base(0)
     }
     
     pub fn enumerate_devices(&mut self) -> Result<(), &'static str> {
@@ -296,6 +307,12 @@ impl PcieController {
     fn enumerate_device(&mut self, segment: u16, bus: u8, device: u8, function: u8) -> Result<(), &'static str> {
         let location = PciLocation::new(segment, bus, device, function);
         
+        // Buses behind a bridge are scanned from the bridge, and again by
+        // the scan of every bus number
+        if self.devices.iter().any(|d| d.location == location) {
+            return Ok(());
+        }
+        
         let vendor_id = self.read16(location, PCI_VENDOR_ID);
         let device_id = self.read16(location, PCI_DEVICE_ID);
         let class = self.read8(location, PCI_CLASS);
@@ -336,7 +353,7 @@ impl PcieController {
         
         // If this is a bridge, enumerate the secondary bus
         if class == PCI_CLASS_BRIDGE && (subclass == 0x04 || subclass == 0x09) {
-            let secondary_bus = self.read8(location, 0x19);
+            let secondary_bus = self.read8(location, PCI_SECONDARY_BUS);
             if secondary_bus != 0 {
                 self.enumerate_bus(segment, secondary_bus)?;
             }
@@ -380,23 +397,32 @@ impl PcieController {
     fn enumerate_extended_capabilities(&self, location: PciLocation) -> Result<Vec<PciExtendedCapability>, &'static str> {
         let mut capabilities = Vec::new();
         
+        // Extended configuration space is only reachable through MMCONFIG
+        if !self.has_extended_config() {
+            return Ok(capabilities);
+        }
+        
         // Extended capabilities start at offset 0x100
         let mut cap_offset = 0x100u16;
         
-        loop {
-            let cap_header = self.read32(location, cap_offset as u8);
+        // A malformed list could loop; there is room for at most this many
+        for _ in 0..(0x1000 - 0x100) / 4 {
+            let cap_header = self.read_ext32(location, cap_offset);
             if cap_header == 0 || cap_header == 0xFFFFFFFF {
                 break;
             }
             
             let cap_id = (cap_header & 0xFFFF) as u16;
             let cap_version = ((cap_header >> 16) & 0x0F) as u8;
-            let next_offset = ((cap_header >> 20) & 0xFFF) as u16;
+            let next_offset = ((cap_header >> 20) & 0xFFC) as u16;
             
             // Read capability data
             let mut data = Vec::new();
-            for i in 0..64 {
-                data.push(self.read8(location, (cap_offset + i) as u8));
+            for i in (0..64u16).step_by(4) {
+                if cap_offset + i > 0xFFC {
+                    break;
+                }
+                data.extend_from_slice(&self.read_ext32(location, cap_offset + i).to_le_bytes());
             }
             
             capabilities.push(PciExtendedCapability {
@@ -406,7 +432,7 @@ impl PcieController {
                 data,
             });
             
-            if next_offset == 0 {
+            if next_offset < 0x100 {
                 break;
             }
             cap_offset = next_offset;
@@ -463,6 +489,38 @@ impl PcieController {
         }
     }
     
+    /// Whether registers past the first 256 bytes, where the extended
+    /// capabilities are, can be reached
+    pub fn has_extended_config(&self) -> bool {
+        matches!(self.access_method, PciAccessMethod::MemoryMapped)
+    }
+    
+    /// Read a dword anywhere in the 4 KiB configuration space; reads past
+    /// the first 256 bytes return all ones without MMCONFIG
+    pub fn read_ext32(&self, location: PciLocation, offset: u16) -> u32 {
+        match (offset, self.access_method) {
+            (0..=0xFF, _) => self.read32(location, offset as u8),
+            (_, PciAccessMethod::MemoryMapped) if offset <= 0xFFC => unsafe {
+                let addr = self.mmconfig_address(location, 0) + (offset & !3) as u64;
+                ((PHYS_MEM_OFFSET + addr) as *const u32).read_volatile()
+            },
+            _ => 0xFFFFFFFF,
+        }
+    }
+    
+    /// Write a dword anywhere in the 4 KiB configuration space; writes past
+    /// the first 256 bytes are dropped without MMCONFIG
+    pub fn write_ext32(&self, location: PciLocation, offset: u16, value: u32) {
+        match (offset, self.access_method) {
+            (0..=0xFF, _) => self.write32(location, offset as u8, value),
+            (_, PciAccessMethod::MemoryMapped) if offset <= 0xFFC => unsafe {
+                let addr = self.mmconfig_address(location, 0) + (offset & !3) as u64;
+                ((PHYS_MEM_OFFSET + addr) as *mut u32).write_volatile(value);
+            },
+            _ => {}
+        }
+    }
+    
     // Legacy I/O port access
     fn legacy_read8(&self, location: PciLocation, register: u8) -> u8 {
         let value = self.legacy_read32(location, register & 0xFC);
@@ -595,6 +653,29 @@ impl PcieController {
         }
     }
     
+    /// Every function found, bridges before the devices behind them
+    pub fn devices(&self) -> &[PciDevice] {
+        &self.devices
+    }
+    
+    /// Scan a bus again after a slot on it was filled, returning the
+    /// functions that were not there before, bridges first
+    pub fn rescan_bus(&mut self, segment: u16, bus: u8) -> Result<Vec<PciDevice>, &'static str> {
+        let known = self.devices.len();
+        self.enumerate_bus(segment, bus)?;
+        Ok(self.devices[known..].to_vec())
+    }
+    
+    /// Forget the functions on a range of buses, as when the slot they were
+    /// behind was emptied, returning them
+    pub fn remove_buses(&mut self, segment: u16, buses: core::ops::RangeInclusive<u8>) -> Vec<PciDevice> {
+        let (removed, kept): (Vec<PciDevice>, Vec<PciDevice>) = core::mem::take(&mut self.devices)
+            .into_iter()
+            .partition(|d| d.location.segment == segment && buses.contains(&d.location.bus));
+        self.devices = kept;
+        removed
+    }
+    
     pub fn find_devices_by_class(&self, class: u8, subclass: Option<u8>) -> Vec<&PciDevice> {
         self.devices.iter()
             .filter(|d| d.class == class && subclass.map_or(true, |sc| d.subclass == sc))
@@ -617,6 +698,31 @@ impl PcieController {
         command &= !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER);
         self.write16(device.location, PCI_COMMAND, command);
     }
+    
+    /// Move a function's BAR, with decoding off while its halves are
+    /// written, and keep the copy in `devices` up to date
+    pub fn move_bar(&mut self, location: PciLocation, index: usize, address: u64) {
+        let register = PCI_BAR0 + index as u8 * 4;
+        let command = self.read16(location, PCI_COMMAND);
+        self.write16(location, PCI_COMMAND, command & !(PCI_COMMAND_IO | PCI_COMMAND_MEMORY));
+        let value = self.read32(location, register);
+        let wide = value & 0x7 == 0x4;
+        let flags = if value & 1 == 1 { 0x3 } else { 0xF };
+        self.write32(location, register, address as u32 & !flags | value & flags);
+        if wide {
+            self.write32(location, register + 4, (address >> 32) as u32);
+        }
+        self.write16(location, PCI_COMMAND, command);
+        
+        let low = self.read32(location, register);
+        let high = if wide { self.read32(location, register + 4) } else { 0 };
+        if let Some(device) = self.devices.iter_mut().find(|device| device.location == location) {
+            device.bars[index] = low;
+            if wide && index < 5 {
+                device.bars[index + 1] = high;
+            }
+        }
+    }
 }
 
 impl PciDevice {
@@ -715,6 +821,51 @@ impl PciDevice {
     pub fn get_extended_capability(&self, cap_id: u16) -> Option<&PciExtendedCapability> {
         self.extended_capabilities.iter().find(|c| c.id == cap_id)
     }
+    
+    /// What the class code says the function is, as lspci names it
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_SCSI) => "SCSI storage controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_IDE) => "IDE interface",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_FLOPPY) => "Floppy disk controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_RAID) => "RAID bus controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_ATA) => "ATA controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_SATA) => "SATA controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_SAS) => "Serial Attached SCSI controller",
+            (PCI_CLASS_STORAGE, PCI_SUBCLASS_STORAGE_NVME) => "Non-Volatile memory controller",
+            (PCI_CLASS_STORAGE, _) => "Mass storage controller",
+            (PCI_CLASS_NETWORK, 0x00) => "Ethernet controller",
+            (PCI_CLASS_NETWORK, _) => "Network controller",
+            (PCI_CLASS_DISPLAY, 0x00) => "VGA compatible controller",
+            (PCI_CLASS_DISPLAY, _) => "Display controller",
+            (PCI_CLASS_MULTIMEDIA, 0x01) => "Multimedia audio controller",
+            (PCI_CLASS_MULTIMEDIA, 0x03) => "Audio device",
+            (PCI_CLASS_MULTIMEDIA, _) => "Multimedia controller",
+            (PCI_CLASS_MEMORY, _) => "Memory controller",
+            (PCI_CLASS_BRIDGE, 0x00) => "Host bridge",
+            (PCI_CLASS_BRIDGE, 0x01) => "ISA bridge",
+            (PCI_CLASS_BRIDGE, 0x04) => "PCI bridge",
+            (PCI_CLASS_BRIDGE, 0x09) => "PCI bridge",
+            (PCI_CLASS_BRIDGE, _) => "Bridge",
+            (PCI_CLASS_COMMUNICATION, 0x00) => "Serial controller",
+            (PCI_CLASS_COMMUNICATION, _) => "Communication controller",
+            (PCI_CLASS_SYSTEM, 0x00) => "PIC",
+            (PCI_CLASS_SYSTEM, 0x03) => "RTC",
+            (PCI_CLASS_SYSTEM, _) => "System peripheral",
+            (PCI_CLASS_INPUT, _) => "Input device controller",
+            (PCI_CLASS_DOCKING, _) => "Docking station",
+            (PCI_CLASS_PROCESSOR, _) => "Processor",
+            (PCI_CLASS_SERIAL_BUS, 0x03) => "USB controller",
+            (PCI_CLASS_SERIAL_BUS, 0x05) => "SMBus",
+            (PCI_CLASS_SERIAL_BUS, _) => "Serial bus controller",
+            (PCI_CLASS_WIRELESS, _) => "Wireless controller",
+            (PCI_CLASS_INTELLIGENT, _) => "Intelligent controller",
+            (PCI_CLASS_SATELLITE, _) => "Satellite communications controller",
+            (PCI_CLASS_ENCRYPTION, _) => "Encryption controller",
+            (PCI_CLASS_SIGNAL_PROCESSING, _) => "Signal processing controller",
+            _ => "Unclassified device",
+        }
+    }
 }
 
 lazy_static! {
@@ -725,6 +876,24 @@ pub fn init() -> Result<(), &'static str> {
     PCIE_CONTROLLER.lock().init()
 }
 
+// Slot and error status are polled, as interrupts are off
+const POLL_INTERVAL_NS: u64 = 100_000_000;
+
+static NEXT_POLL: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
+
+/// Act on hot-plug events and log reported errors; called from the main loop
+pub fn poll() {
+    use core::sync::atomic::Ordering;
+    
+    let now = crate::time::clocksource::now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    hotplug::poll(now);
+    aer::poll();
+}
+
 pub fn enumerate_devices() {
     let controller = PCIE_CONTROLLER.lock();
     
@@ -1,12 +1,13 @@
 // Performance monitoring and profiling subsystem
 // Provides CPU performance counters, profiling, and latency tracking
 
-use core::sync::atomic::{AtomicU64, AtomicU32, Ordering};
-use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU32, AtomicU8, Ordering};
 use alloc::string::String;
 use spin::Mutex;
 use lazy_static::lazy_static;
-use crate::process::{PROCESS_MANAGER, thread::THREAD_MANAGER};
+use x86_64::structures::idt::InterruptStackFrame;
+
+pub mod sampler;
 
 // Performance monitoring counter (PMC) MSRs
 const IA32_PERFEVTSEL0: u32 = 0x186;
@@ -104,11 +105,161 @@ impl PerfEventSelect {
     }
 }
 
+// Architectural performance monitoring as reported by CPUID leaf 0xA. AMD
+// and most emulated CPUs report version 0: no counters to program.
+#[derive(Debug, Clone, Copy)]
+pub struct PmuInfo {
+    pub version: u8,
+    pub counters: u8,
+    pub counter_width: u8,
+}
+
+pub fn pmu_info() -> PmuInfo {
+    if core::arch::x86_64::__cpuid(0).eax < 0xA {
+        return PmuInfo { version: 0, counters: 0, counter_width: 0 };
+    }
+    let leaf = core::arch::x86_64::__cpuid(0xA);
+    PmuInfo {
+        version: leaf.eax as u8,
+        counters: (leaf.eax >> 8) as u8,
+        counter_width: (leaf.eax >> 16) as u8,
+    }
+}
+
+// Detected PMU, readable from NMI context without the PMU lock
+static PMU_VERSION: AtomicU8 = AtomicU8::new(0);
+static PMU_COUNTERS: AtomicU8 = AtomicU8::new(0);
+static PMU_WIDTH: AtomicU8 = AtomicU8::new(0);
+
+const APIC_BASE_ADDR: u64 = 0xFEE00000;
+const APIC_LVT_PERF: u32 = 0x340;
+// Overflows are delivered as NMIs, so that sampling and the lockup watchdog
+// see code running with interrupts disabled. Delivery masks the entry.
+const APIC_LVT_NMI: u32 = 4 << 8;
+
+fn route_overflow_nmi() {
+    unsafe {
+        let apic_ptr = APIC_BASE_ADDR as *mut u32;
+        apic_ptr.add((APIC_LVT_PERF / 4) as usize).write_volatile(APIC_LVT_NMI);
+    }
+}
+
+/// Counters the NMI overflow interrupt can be shared between
+pub fn sampling_counters() -> usize {
+    (PMU_COUNTERS.load(Ordering::Relaxed) as usize).min(4)
+}
+
+// A counter written through IA32_PMCx takes the low 32 bits sign-extended,
+// so a sampling period must fit in 31 bits
+pub const MAX_SAMPLE_PERIOD: u64 = 0x7fff_ffff;
+
+/// Program a counter of this CPU to overflow, and raise a performance
+/// monitoring interrupt, every `period` events
+pub fn arm_sampling_counter(counter: usize, event: PerfEvent, period: u64) {
+    let version = PMU_VERSION.load(Ordering::Relaxed);
+    if version == 0 || counter >= 4 {
+        return;
+    }
+
+    let event_select = PerfEventSelect {
+        event_select: (event.to_event_select() & 0xFF) as u8,
+        unit_mask: ((event.to_event_select() >> 8) & 0xFF) as u8,
+        usr: true,
+        os: true,
+        edge: false,
+        pc: false,
+        interrupt: true,
+        enable: true,
+        invert: false,
+        counter_mask: 0,
+    };
+
+    crate::cpu::write_msr(IA32_PERFEVTSEL0 + counter as u32, 0);
+    reload_sampling_counter(counter, period);
+    route_overflow_nmi();
+    crate::cpu::write_msr(IA32_PERFEVTSEL0 + counter as u32, event_select.to_msr_value());
+    if version >= 2 {
+        let enabled = crate::cpu::read_msr(IA32_PERF_GLOBAL_CTRL);
+        crate::cpu::write_msr(IA32_PERF_GLOBAL_CTRL, enabled | 1 << counter);
+    }
+}
+
+/// Acknowledge an overflow and start the next sampling period
+pub fn reload_sampling_counter(counter: usize, period: u64) {
+    let version = PMU_VERSION.load(Ordering::Relaxed);
+    if version == 0 || counter >= 4 {
+        return;
+    }
+
+    let period = period.clamp(1, MAX_SAMPLE_PERIOD);
+    if version >= 2 {
+        crate::cpu::write_msr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << counter);
+    }
+    crate::cpu::write_msr(IA32_PMC0 + counter as u32, (period as i64).wrapping_neg() as u64);
+}
+
+pub fn disarm_counter(counter: usize) {
+    let version = PMU_VERSION.load(Ordering::Relaxed);
+    if version == 0 || counter >= 4 {
+        return;
+    }
+
+    crate::cpu::write_msr(IA32_PERFEVTSEL0 + counter as u32, 0);
+    if version >= 2 {
+        let enabled = crate::cpu::read_msr(IA32_PERF_GLOBAL_CTRL);
+        crate::cpu::write_msr(IA32_PERF_GLOBAL_CTRL, enabled & !(1 << counter));
+        crate::cpu::write_msr(IA32_PERF_GLOBAL_OVF_CTRL, 1 << counter);
+    }
+}
+
+// Counters of this CPU that overflowed
+fn overflowed_counters() -> u64 {
+    match PMU_VERSION.load(Ordering::Relaxed) {
+        0 => 0,
+        1 => {
+            // No global status: an interrupting counter has overflowed when
+            // it wrapped from its negative start past zero
+            let top_bit = (PMU_WIDTH.load(Ordering::Relaxed) as u32).clamp(32, 64) - 1;
+            (0..sampling_counters())
+                .filter(|&counter| {
+                    crate::cpu::read_msr(IA32_PERFEVTSEL0 + counter as u32) & (1 << 20) != 0
+                        && crate::cpu::read_msr(IA32_PMC0 + counter as u32) >> top_bit & 1 == 0
+                })
+                .fold(0, |overflowed, counter| overflowed | 1 << counter)
+        }
+        _ => crate::cpu::read_msr(IA32_PERF_GLOBAL_STATUS) & 0xF,
+    }
+}
+
+/// Called from the NMI handler: dispatch counter overflows on this CPU to
+/// the sampler and the lockup watchdog. Returns false when no counter
+/// overflowed, so the NMI came from somewhere else.
+pub fn handle_overflow_nmi(frame: &InterruptStackFrame, frame_pointer: u64) -> bool {
+    let overflowed = overflowed_counters();
+    if overflowed == 0 {
+        return false;
+    }
+
+    if overflowed & 1 << sampler::SAMPLING_COUNTER != 0 {
+        sampler::counter_overflow(frame, frame_pointer);
+    }
+    if overflowed & 1 << crate::debug::watchdog::WATCHDOG_COUNTER != 0 {
+        crate::debug::watchdog::counter_overflow(frame, frame_pointer);
+    }
+
+    if PMU_VERSION.load(Ordering::Relaxed) >= 2 {
+        crate::cpu::write_msr(IA32_PERF_GLOBAL_OVF_CTRL, overflowed);
+    }
+    route_overflow_nmi();
+    true
+}
+
 // Performance monitoring unit (PMU) state
 pub struct PMU {
     counters: [AtomicU64; 4],
     events: [Option<PerfEvent>; 4],
     enabled: AtomicU32,
+    info: PmuInfo,
 }
 
 impl PMU {
@@ -122,10 +273,20 @@ impl PMU {
             ],
             events: [None; 4],
             enabled: AtomicU32::new(0),
+            info: PmuInfo { version: 0, counters: 0, counter_width: 0 },
         }
     }
     
     pub fn init(&mut self) {
+        self.info = pmu_info();
+        if self.info.version == 0 {
+            crate::serial_println!("PMU not available, profiling falls back to the timer");
+            return;
+        }
+        PMU_VERSION.store(self.info.version, Ordering::Relaxed);
+        PMU_COUNTERS.store(self.info.counters, Ordering::Relaxed);
+        PMU_WIDTH.store(self.info.counter_width, Ordering::Relaxed);
+
         // Enable performance monitoring in CR4
         unsafe {
             let mut cr4: u64;
@@ -137,11 +298,20 @@ impl PMU {
         // Reset all counters
         self.reset_all();
         
-        crate::serial_println!("PMU initialized with {} counters", 4);
+        crate::serial_println!("PMU v{} initialized with {} counters of {} bits",
+            self.info.version, self.info.counters, self.info.counter_width);
+    }
+
+    pub fn available(&self) -> bool {
+        self.info.version > 0
+    }
+
+    fn counter_count(&self) -> usize {
+        (self.info.counters as usize).min(4)
     }
     
     pub fn configure_counter(&mut self, counter: usize, event: PerfEvent) -> Result<(), &'static str> {
-        if counter >= 4 {
+        if counter >= self.counter_count() {
             return Err("Invalid counter index");
         }
         
@@ -173,9 +343,37 @@ impl PMU {
         
         Ok(())
     }
+
+    /// Program a counter of this CPU for sampling; see arm_sampling_counter
+    pub fn configure_sampling(&mut self, counter: usize, event: PerfEvent, period: u64) -> Result<(), &'static str> {
+        if counter >= self.counter_count() {
+            return Err("Invalid counter index");
+        }
+        if event.to_event_select() == 0 {
+            return Err("Event has no hardware encoding");
+        }
+
+        arm_sampling_counter(counter, event, period);
+
+        self.events[counter] = Some(event);
+        self.enabled.fetch_or(1 << counter, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    pub fn release_counter(&mut self, counter: usize) {
+        if counter >= self.counter_count() {
+            return;
+        }
+
+        disarm_counter(counter);
+
+        self.events[counter] = None;
+        self.enabled.fetch_and(!(1 << counter), Ordering::SeqCst);
+    }
     
     pub fn read_counter(&self, counter: usize) -> u64 {
-        if counter >= 4 {
+        if counter >= self.counter_count() {
             return 0;
         }
         
@@ -186,6 +384,10 @@ impl PMU {
     }
     
     pub fn start_all(&self) {
+        if self.info.version < 2 {
+            return;
+        }
+
         unsafe {
             // Enable all configured counters
             let enabled = self.enabled.load(Ordering::SeqCst) as u64;
@@ -194,6 +396,10 @@ impl PMU {
     }
     
     pub fn stop_all(&self) {
+        if self.info.version < 2 {
+            return;
+        }
+
         unsafe {
             // Disable all counters
             crate::cpu::write_msr(IA32_PERF_GLOBAL_CTRL, 0);
@@ -203,7 +409,7 @@ impl PMU {
     pub fn reset_all(&mut self) {
         self.stop_all();
         
-        for i in 0..4 {
+        for i in 0..self.counter_count() {
             unsafe {
                 let pmc_msr = IA32_PMC0 + i as u32;
                 crate::cpu::write_msr(pmc_msr, 0);
@@ -217,67 +423,6 @@ lazy_static! {
     pub static ref PMU_INSTANCE: Mutex<PMU> = Mutex::new(PMU::new());
 }
 
-// Profiling sample
-#[derive(Debug, Clone)]
-pub struct ProfileSample {
-    pub timestamp: u64,
-    pub rip: u64,
-    pub rsp: u64,
-    pub pid: u32,
-    pub tid: u32,
-    pub cpu: u32,
-    pub in_kernel: bool,
-}
-
-// Profiling buffer
-pub struct ProfileBuffer {
-    samples: Vec<ProfileSample>,
-    max_samples: usize,
-    current_index: AtomicU32,
-    overflow_count: AtomicU32,
-}
-
-impl ProfileBuffer {
-    pub fn new(max_samples: usize) -> Self {
-        Self {
-            samples: Vec::with_capacity(max_samples),
-            max_samples,
-            current_index: AtomicU32::new(0),
-            overflow_count: AtomicU32::new(0),
-        }
-    }
-    
-    pub fn add_sample(&mut self, sample: ProfileSample) {
-        let index = self.current_index.fetch_add(1, Ordering::SeqCst) as usize;
-        
-        if index < self.max_samples {
-            if index >= self.samples.len() {
-                self.samples.push(sample);
-            } else {
-                self.samples[index] = sample;
-            }
-        } else {
-            self.overflow_count.fetch_add(1, Ordering::SeqCst);
-        }
-    }
-    
-    pub fn get_samples(&self) -> &[ProfileSample] {
-        let count = self.current_index.load(Ordering::SeqCst) as usize;
-        &self.samples[..count.min(self.samples.len())]
-    }
-    
-    pub fn clear(&mut self) {
-        self.samples.clear();
-        self.current_index.store(0, Ordering::SeqCst);
-        self.overflow_count.store(0, Ordering::SeqCst);
-    }
-}
-
-lazy_static! {
-    pub static ref PROFILE_BUFFER: Mutex<ProfileBuffer> = 
-        Mutex::new(ProfileBuffer::new(10000));
-}
-
 // Latency tracking
 pub struct LatencyTracker {
     pub name: String,
@@ -468,109 +613,17 @@ pub fn print_performance_summary() {
     println!("  Preemption: avg={} cycles", preempt_stats.avg_cycles);
 }
 
-// Enable profiling
+// Start sampling instructions retired on every CPU
 pub fn enable_profiling(sample_period: u64) {
-    let mut pmu = PMU_INSTANCE.lock();
-    
-    // Configure counter 0 for instruction retired with interrupt on overflow
-    pmu.configure_counter(0, PerfEvent::Instructions).unwrap();
-    
-    // Set sample period
-    unsafe {
-        crate::cpu::write_msr(IA32_PMC0, -(sample_period as i64) as u64);
+    match sampler::start(sampler::SampleSource::Instructions, sample_period) {
+        Ok(()) => crate::serial_println!("Profiling enabled with period {}", sample_period),
+        Err(error) => crate::serial_println!("Profiling not enabled: {}", error),
     }
-    
-    pmu.start_all();
-    
-    crate::serial_println!("Profiling enabled with period {}", sample_period);
 }
 
 // Disable profiling
 pub fn disable_profiling() {
-    let pmu = PMU_INSTANCE.lock();
-    pmu.stop_all();
-    
-    crate::serial_println!("Profiling disabled");
-}
+    sampler::stop();
 
-// Performance monitoring interrupt handler
-pub extern "x86-interrupt" fn pmu_interrupt_handler(
-    stack_frame: x86_64::structures::idt::InterruptStackFrame
-) {
-    // Get current process and thread IDs in a thread-safe manner
-    let (pid, tid) = get_current_context();
-    
-    // Collect sample
-    let sample = ProfileSample {
-        timestamp: crate::timer::rdtsc(),
-        rip: stack_frame.instruction_pointer.as_u64(),
-        rsp: stack_frame.stack_pointer.as_u64(),
-        pid,
-        tid,
-        cpu: crate::cpu::get_cpu_id(),
-        in_kernel: stack_frame.code_segment == 0x08,
-    };
-    
-    // Add to profile buffer
-    if let Some(mut buffer) = PROFILE_BUFFER.try_lock() {
-        buffer.add_sample(sample);
-    }
-    
-    // Reset counter for next sample
-    unsafe {
-        // Clear overflow flag
-        crate::cpu::write_msr(IA32_PERF_GLOBAL_OVF_CTRL, 0x1);
-        
-        // Reset counter with sample period
-        let sample_period = 1000000; // Sample every million instructions
-        crate::cpu::write_msr(IA32_PMC0, -(sample_period as i64) as u64);
-    }
-    
-    // Send EOI
-    crate::interrupts::send_eoi_apic();
+    crate::serial_println!("Profiling disabled");
 }
-
-// Helper function to get current process and thread context
-// Returns (PID, TID) in a thread-safe manner
-fn get_current_context() -> (u32, u32) {
-    // Try to get thread ID first, as it's more specific
-    let tid = if let Some(thread_manager) = THREAD_MANAGER.try_lock() {
-        thread_manager.get_current_thread()
-            .map(|tid| tid.0)
-            .unwrap_or(0)
-    } else {
-        // If we can't lock, return 0 to avoid blocking in interrupt context
-        0
-    };
-    
-    // Get process ID
-    let pid = if let Some(process_manager) = PROCESS_MANAGER.try_lock() {
-        if let Some(current_pid) = process_manager.current_process {
-            current_pid.0
-        } else {
-            // Check if we have a thread and can get its process
-            if tid != 0 {
-                if let Some(thread_manager) = THREAD_MANAGER.try_lock() {
-                    if let Some(thread_id) = thread_manager.get_current_thread() {
-                        if let Some(thread) = thread_manager.get_thread(thread_id) {
-                            thread.process_id.0
-                        } else {
-                            0
-                        }
-                    } else {
-                        0
-                    }
-                } else {
-                    0
-                }
-            } else {
-                0
-            }
-        }
-    } else {
-        // If we can't lock, return 0 to avoid blocking in interrupt context
-        0
-    };
-    
-    (pid, tid)
-}
\ No newline at end of file
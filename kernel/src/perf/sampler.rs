@@ -0,0 +1,406 @@
+// Sampling Profiler
+// Counter 0 of each CPU's PMU is programmed to overflow every `period`
+// events and raise an NMI. The NMI records the interrupted instruction
+// pointer and, for kernel code, its frame pointer call chain into that
+// CPU's buffer. Without an architectural PMU (AMD, or QEMU without KVM) the
+// timer tick drives sampling instead.
+//
+// Samples aggregate by symbol into a flat profile, and fold into
+// "outer;inner;leaf count" lines: the input of flamegraph.pl and inferno.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{PerfEvent, MAX_SAMPLE_PERIOD, PMU_INSTANCE};
+use crate::debug::unwind::{self, Frame};
+use crate::println;
+use crate::smp::MAX_CPUS;
+
+pub const MAX_DEPTH: usize = 16;
+pub const SAMPLING_COUNTER: usize = 0;
+const SAMPLES_PER_CPU: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SampleSource {
+    Cycles,
+    Instructions,
+    CacheMisses,
+    Timer,
+}
+
+impl SampleSource {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cycles" => Some(SampleSource::Cycles),
+            "instructions" => Some(SampleSource::Instructions),
+            "cache-misses" => Some(SampleSource::CacheMisses),
+            "timer" => Some(SampleSource::Timer),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleSource::Cycles => "cycles",
+            SampleSource::Instructions => "instructions",
+            SampleSource::CacheMisses => "cache-misses",
+            SampleSource::Timer => "timer",
+        }
+    }
+
+    /// Events per sample, or timer ticks per sample for the timer
+    pub fn default_period(self) -> u64 {
+        match self {
+            SampleSource::Cycles | SampleSource::Instructions => 1_000_000,
+            SampleSource::CacheMisses => 1_000,
+            SampleSource::Timer => 1,
+        }
+    }
+
+    fn event(self) -> Option<PerfEvent> {
+        match self {
+            SampleSource::Cycles => Some(PerfEvent::CpuCycles),
+            SampleSource::Instructions => Some(PerfEvent::Instructions),
+            SampleSource::CacheMisses => Some(PerfEvent::CacheMisses),
+            SampleSource::Timer => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => SampleSource::Cycles,
+            1 => SampleSource::Instructions,
+            2 => SampleSource::CacheMisses,
+            _ => SampleSource::Timer,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    ip: u64,
+    user: bool,
+    depth: u8,
+    // Return addresses, innermost first
+    callchain: [u64; MAX_DEPTH],
+}
+
+// Filled only by the CPU it belongs to, from its sampling interrupt; the
+// capacity is reserved up front so recording never allocates
+struct CpuSamples {
+    samples: Vec<Sample>,
+    lost: u64,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static SOURCE: AtomicU8 = AtomicU8::new(SampleSource::Timer as u8);
+static PERIOD: AtomicU64 = AtomicU64::new(1);
+// Samples dropped because the NMI hit while the CPU's buffer was locked
+static CONTENDED: AtomicU64 = AtomicU64::new(0);
+
+// Bumped on every start and stop. A CPU whose ARMED value is behind brings
+// its counter in line on its next timer tick.
+static SESSION: AtomicU64 = AtomicU64::new(0);
+static ARMED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+lazy_static! {
+    static ref BUFFERS: Vec<Mutex<CpuSamples>> = (0..MAX_CPUS)
+        .map(|_| Mutex::new(CpuSamples { samples: Vec::new(), lost: 0 }))
+        .collect();
+}
+
+fn current_cpu() -> usize {
+    crate::smp::current_cpu_id() as usize % MAX_CPUS
+}
+
+fn source() -> SampleSource {
+    SampleSource::from_u8(SOURCE.load(Ordering::Relaxed))
+}
+
+/// Start sampling on every CPU: this one now, the others from their next
+/// timer tick. `period` counts events, or timer ticks for the timer source.
+pub fn start(source: SampleSource, period: u64) -> Result<(), &'static str> {
+    if period == 0 || period > MAX_SAMPLE_PERIOD {
+        return Err("sample period out of range");
+    }
+    if RUNNING.load(Ordering::SeqCst) {
+        return Err("profiler already running");
+    }
+    let mut pmu = PMU_INSTANCE.lock();
+    if source.event().is_some() && !pmu.available() {
+        return Err("no architectural PMU, use the timer source");
+    }
+
+    let mut cpus = crate::smp::SMP_MANAGER.lock().get_online_cpus();
+    let cpu = current_cpu();
+    if !cpus.contains(&(cpu as u32)) {
+        cpus.push(cpu as u32);
+    }
+    for cpu in cpus {
+        if let Some(buffer) = BUFFERS.get(cpu as usize) {
+            let mut buffer = buffer.lock();
+            buffer.samples.clear();
+            buffer.samples.reserve_exact(SAMPLES_PER_CPU);
+            buffer.lost = 0;
+        }
+    }
+    CONTENDED.store(0, Ordering::SeqCst);
+
+    SOURCE.store(source as u8, Ordering::SeqCst);
+    PERIOD.store(period, Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+    let session = SESSION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let armed = x86_64::instructions::interrupts::without_interrupts(|| {
+        ARMED[cpu].store(session, Ordering::SeqCst);
+        match source.event() {
+            Some(event) => pmu.configure_sampling(SAMPLING_COUNTER, event, period),
+            None => Ok(()),
+        }
+    });
+    if let Err(error) = armed {
+        RUNNING.store(false, Ordering::SeqCst);
+        SESSION.fetch_add(1, Ordering::SeqCst);
+        return Err(error);
+    }
+
+    crate::serial_println!("[PROFILE] Sampling {} every {}", source.name(), period);
+    Ok(())
+}
+
+pub fn stop() {
+    if !RUNNING.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let session = SESSION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    let mut pmu = PMU_INSTANCE.lock();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        ARMED[current_cpu()].store(session, Ordering::SeqCst);
+        if source().event().is_some() {
+            pmu.release_counter(SAMPLING_COUNTER);
+        }
+    });
+    drop(pmu);
+
+    let (samples, lost) = sample_count();
+    crate::serial_println!("[PROFILE] Sampling stopped: {} samples, {} lost", samples, lost);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Source and period of the current or last session
+pub fn settings() -> (SampleSource, u64) {
+    (source(), PERIOD.load(Ordering::Relaxed))
+}
+
+/// (samples recorded, samples lost) across all CPUs
+pub fn sample_count() -> (u64, u64) {
+    let mut samples = 0;
+    let mut lost = CONTENDED.load(Ordering::Relaxed);
+    for buffer in BUFFERS.iter() {
+        let buffer = buffer.lock();
+        samples += buffer.samples.len() as u64;
+        lost += buffer.lost;
+    }
+    (samples, lost)
+}
+
+// Bring this CPU's counter in line with the current session
+fn sync_counter() {
+    match source().event() {
+        Some(event) if RUNNING.load(Ordering::Acquire) => {
+            super::arm_sampling_counter(SAMPLING_COUNTER, event, PERIOD.load(Ordering::Relaxed));
+        }
+        _ => super::disarm_counter(SAMPLING_COUNTER),
+    }
+}
+
+/// Called from the timer interrupt of every CPU
+pub fn timer_tick(frame: &InterruptStackFrame, frame_pointer: u64) {
+    let cpu = current_cpu();
+    let session = SESSION.load(Ordering::Acquire);
+    if ARMED[cpu].swap(session, Ordering::AcqRel) != session {
+        sync_counter();
+    }
+
+    if RUNNING.load(Ordering::Relaxed) && source() == SampleSource::Timer {
+        let ticks = TICKS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+        if ticks % PERIOD.load(Ordering::Relaxed) == 0 {
+            record(cpu, frame, frame_pointer);
+        }
+    }
+}
+
+/// The sampling counter of this CPU overflowed (NMI context)
+pub fn counter_overflow(frame: &InterruptStackFrame, frame_pointer: u64) {
+    let cpu = current_cpu();
+
+    if RUNNING.load(Ordering::Acquire) && ARMED[cpu].load(Ordering::Relaxed) == SESSION.load(Ordering::Acquire) {
+        record(cpu, frame, frame_pointer);
+        super::reload_sampling_counter(SAMPLING_COUNTER, PERIOD.load(Ordering::Relaxed));
+    } else {
+        // Left over from an earlier session; the next tick rearms if needed
+        super::disarm_counter(SAMPLING_COUNTER);
+    }
+}
+
+fn record(cpu: usize, frame: &InterruptStackFrame, frame_pointer: u64) {
+    let user = frame.code_segment & 3 == 3;
+    let mut sample = Sample {
+        ip: frame.instruction_pointer.as_u64(),
+        user,
+        depth: 0,
+        callchain: [0; MAX_DEPTH],
+    };
+    // User code need not keep frame pointers; only kernel stacks are walked
+    if !user {
+        let start = Frame::new(sample.ip, frame.stack_pointer.as_u64(), frame_pointer);
+        sample.depth = unwind::frame_pointer_chain(start, &mut sample.callchain) as u8;
+    }
+
+    match BUFFERS[cpu].try_lock() {
+        Some(mut buffer) => {
+            if buffer.samples.len() < buffer.samples.capacity() {
+                buffer.samples.push(sample);
+            } else {
+                buffer.lost += 1;
+            }
+        }
+        None => {
+            CONTENDED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Symbol names by address, so each distinct address is resolved once
+struct Symbolizer {
+    names: BTreeMap<u64, String>,
+}
+
+impl Symbolizer {
+    fn new() -> Self {
+        Self { names: BTreeMap::new() }
+    }
+
+    fn name(&mut self, address: u64, user: bool) -> String {
+        self.names
+            .entry(address)
+            .or_insert_with(|| match crate::debug::symbols::SYMBOLS.resolve(address) {
+                // Folded stacks separate frames with ';'
+                Some(resolved) if resolved.symbol.module == "kernel" => resolved.symbol.name.replace(';', ":"),
+                Some(resolved) => format!("{}!{}", resolved.symbol.module, resolved.symbol.name).replace(';', ":"),
+                None if user => String::from("[user]"),
+                None => String::from("[unknown]"),
+            })
+            .clone()
+    }
+
+    // Frames of a sample, outermost first
+    fn stack(&mut self, sample: &Sample) -> Vec<String> {
+        // A return address points after the call; look up the call itself
+        let mut frames: Vec<String> = sample.callchain[..sample.depth as usize]
+            .iter()
+            .rev()
+            .map(|&address| self.name(address - 1, false))
+            .collect();
+        frames.push(self.name(sample.ip, sample.user));
+        frames
+    }
+}
+
+fn samples() -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for buffer in BUFFERS.iter() {
+        samples.extend_from_slice(&buffer.lock().samples);
+    }
+    samples
+}
+
+pub struct ProfileEntry {
+    pub symbol: String,
+    /// Samples in the function itself
+    pub self_samples: u64,
+    /// Samples in the function or anything it called
+    pub total_samples: u64,
+}
+
+/// Samples per symbol, most self samples first
+pub fn flat_profile() -> Vec<ProfileEntry> {
+    let mut symbols = Symbolizer::new();
+    let mut counts: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+
+    for sample in samples() {
+        let mut frames = symbols.stack(&sample);
+        if let Some(leaf) = frames.last() {
+            counts.entry(leaf.clone()).or_default().0 += 1;
+        }
+        // Recursive functions count once per sample
+        frames.sort();
+        frames.dedup();
+        for frame in frames {
+            counts.entry(frame).or_default().1 += 1;
+        }
+    }
+
+    let mut entries: Vec<ProfileEntry> = counts
+        .into_iter()
+        .map(|(symbol, (self_samples, total_samples))| ProfileEntry { symbol, self_samples, total_samples })
+        .collect();
+    entries.sort_by(|a, b| {
+        b.self_samples.cmp(&a.self_samples).then(b.total_samples.cmp(&a.total_samples))
+    });
+    entries
+}
+
+/// Collapsed stacks, one "outer;inner;leaf count" line per distinct stack
+pub fn folded() -> String {
+    let mut symbols = Symbolizer::new();
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for sample in samples() {
+        *stacks.entry(symbols.stack(&sample).join(";")).or_default() += 1;
+    }
+
+    let mut output = String::new();
+    for (stack, count) in stacks {
+        output.push_str(&format!("{} {}\n", stack, count));
+    }
+    output
+}
+
+/// Write the folded stacks to the serial port, between marker lines
+pub fn dump_folded() {
+    crate::serial_println!("[PROFILE] --- folded stacks begin ---");
+    for line in folded().lines() {
+        crate::serial_println!("{}", line);
+    }
+    crate::serial_println!("[PROFILE] --- folded stacks end ---");
+}
+
+pub fn print_report(limit: usize) {
+    let (source, period) = settings();
+    let (samples, lost) = sample_count();
+    println!("Samples: {} ({} lost), {} every {}{}", samples, lost, source.name(), period,
+        if is_running() { ", running" } else { "" });
+    if samples == 0 {
+        return;
+    }
+
+    println!("  Self%  Total%  Symbol");
+    for entry in flat_profile().iter().take(limit) {
+        println!("{:>6.2}% {:>6.2}%  {}",
+            entry.self_samples as f64 * 100.0 / samples as f64,
+            entry.total_samples as f64 * 100.0 / samples as f64,
+            entry.symbol);
+    }
+}
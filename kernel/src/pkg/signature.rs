@@ -1,6 +1,9 @@
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
 use super::{PackageError, Result};
+use crate::crypto::asymmetric::{AsymmetricCrypto, Ed25519, RSA};
+use crate::crypto::{self, SignatureScheme};
 
 const SIGNATURE_MAGIC: &[u8; 4] = b"RSIG";
 const SIGNATURE_VERSION: u16 = 1;
@@ -122,84 +125,66 @@ fn verify_ed25519(data: &[u8], signature: &[u8], public_key: &[u8]) -> Result<bo
         return Ok(false);
     }
 
-    let mut hash = [0u8; 32];
-    simple_hash(data, &mut hash);
-
-    Ok(true)
+    crypto::verify_signature(SignatureScheme::Ed25519, public_key, data, signature)
+        .map_err(|_| PackageError::SignatureVerificationFailed)
 }
 
+// RSA keys use the crypto module's length-prefixed (n, e) encoding
 fn verify_rsa(data: &[u8], signature: &[u8], public_key: &[u8]) -> Result<bool> {
-    let mut hash = [0u8; 32];
-    simple_hash(data, &mut hash);
-
-    Ok(true)
+    crypto::verify_signature(SignatureScheme::RsaPkcs1Sha256, public_key, data, signature)
+        .map_err(|_| PackageError::SignatureVerificationFailed)
 }
 
-fn simple_hash(data: &[u8], output: &mut [u8; 32]) {
-    let mut state = [0u8; 32];
-    
-    for (i, &byte) in data.iter().enumerate() {
-        state[i % 32] ^= byte;
-        state[(i + 1) % 32] = state[(i + 1) % 32].wrapping_add(byte);
+fn signer(algorithm: SignatureAlgorithm) -> Box<dyn AsymmetricCrypto> {
+    match algorithm {
+        SignatureAlgorithm::Ed25519 => Box::new(Ed25519::new()),
+        SignatureAlgorithm::Rsa2048 => Box::new(RSA::new(2048)),
+        SignatureAlgorithm::Rsa4096 => Box::new(RSA::new(4096)),
     }
-    
-    output.copy_from_slice(&state);
 }
 
-pub fn sign_package(data: &[u8], private_key: &[u8], algorithm: SignatureAlgorithm) -> Result<Signature> {
-    let mut signature_data = Vec::new();
-    
+// The public half embedded in a private key: the trailing 32 bytes for
+// Ed25519, the leading (n, e) fields for RSA
+fn public_part(private_key: &[u8], algorithm: SignatureAlgorithm) -> Option<&[u8]> {
     match algorithm {
-        SignatureAlgorithm::Ed25519 => {
-            signature_data.resize(64, 0);
-        }
-        SignatureAlgorithm::Rsa2048 => {
-            signature_data.resize(256, 0);
-        }
-        SignatureAlgorithm::Rsa4096 => {
-            signature_data.resize(512, 0);
+        SignatureAlgorithm::Ed25519 => private_key.get(32..64),
+        SignatureAlgorithm::Rsa2048 | SignatureAlgorithm::Rsa4096 => {
+            let mut offset = 0;
+            for _ in 0..2 {
+                let len = private_key.get(offset..offset + 4)?;
+                offset += 4 + u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            }
+            private_key.get(..offset)
         }
     }
+}
 
-    let mut hash = [0u8; 32];
-    simple_hash(data, &mut hash);
-    
-    for i in 0..32.min(signature_data.len()) {
-        signature_data[i] = hash[i];
-    }
+/// Key ID: the first 8 bytes of SHA-256 over the public key
+pub fn key_id(public_key: &[u8]) -> [u8; 8] {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&crypto::sha256(public_key)[..8]);
+    id
+}
 
-    let mut key_id = [0u8; 8];
-    for i in 0..8.min(private_key.len()) {
-        key_id[i] = private_key[i];
-    }
+pub fn sign_package(data: &[u8], private_key: &[u8], algorithm: SignatureAlgorithm) -> Result<Signature> {
+    let public_key = public_part(private_key, algorithm)
+        .ok_or_else(|| PackageError::InvalidFormat("Invalid private key".to_string()))?;
+    let signature = signer(algorithm)
+        .sign(private_key, data)
+        .map_err(|_| PackageError::InvalidFormat("Signing failed".to_string()))?;
 
     Ok(Signature {
-        key_id,
+        key_id: key_id(public_key),
         algorithm,
-        signature: signature_data,
+        signature,
         timestamp: current_timestamp(),
     })
 }
 
 pub fn generate_keypair(algorithm: SignatureAlgorithm) -> Result<(Vec<u8>, Vec<u8>)> {
-    let (public_size, private_size) = match algorithm {
-        SignatureAlgorithm::Ed25519 => (32, 64),
-        SignatureAlgorithm::Rsa2048 => (256, 256),
-        SignatureAlgorithm::Rsa4096 => (512, 512),
-    };
-
-    let mut public_key = Vec::new();
-    let mut private_key = Vec::new();
-
-    for i in 0..public_size {
-        public_key.push((i * 17 + 23) as u8);
-    }
-
-    for i in 0..private_size {
-        private_key.push((i * 31 + 41) as u8);
-    }
-
-    Ok((public_key, private_key))
+    signer(algorithm)
+        .generate_keypair()
+        .map_err(|_| PackageError::InvalidFormat("Key generation failed".to_string()))
 }
 
 pub fn export_public_key(key: &PublicKey) -> String {
@@ -61,6 +61,19 @@ pub struct CpuFrequencyScaling {
     cpu_count: usize,
     per_core_scaling: bool,
     energy_perf_bias: u8,
+    energy_perf_bias_supported: bool,
+}
+
+/// What `cpufreq` shows: the governor and the frequencies it works within
+#[derive(Debug, Clone, Copy)]
+pub struct CpuFreqStatus {
+    pub governor: CpuGovernor,
+    pub current_mhz: u32,
+    pub min_mhz: u32,
+    pub max_mhz: u32,
+    pub base_mhz: u32,
+    pub turbo_enabled: bool,
+    pub p_states: usize,
 }
 
 impl CpuFrequencyScaling {
@@ -79,6 +92,7 @@ impl CpuFrequencyScaling {
             cpu_count: 1,
             per_core_scaling: false,
             energy_perf_bias: 6, // Balanced
+            energy_perf_bias_supported: false,
         }
     }
 
@@ -119,6 +133,7 @@ impl CpuFrequencyScaling {
             
             if features.has_energy_bias_pref() {
                 self.energy_perf_bias = self.read_energy_perf_bias();
+                self.energy_perf_bias_supported = true;
                 serial_println!("CPUFreq: Energy performance bias supported");
             }
         }
@@ -266,16 +281,33 @@ impl CpuFrequencyScaling {
         Ok(())
     }
 
+    pub fn status(&self) -> CpuFreqStatus {
+        CpuFreqStatus {
+            governor: self.governor,
+            // Without P-states the performance MSRs were never found
+            current_mhz: if self.p_states.is_empty() { 0 } else { self.get_current_frequency() },
+            min_mhz: self.min_frequency,
+            max_mhz: self.max_frequency,
+            base_mhz: self.base_frequency,
+            turbo_enabled: self.turbo_enabled,
+            p_states: self.p_states.len(),
+        }
+    }
+
     fn apply_governor_policy(&mut self) -> Result<(), &'static str> {
         match self.governor {
             CpuGovernor::Performance => {
                 // Set to maximum P-state
-                self.set_pstate(self.p_states.len() - 1)?;
+                if let Some(last) = self.p_states.len().checked_sub(1) {
+                    self.set_pstate(last)?;
+                }
                 self.set_energy_perf_bias(0)?; // Maximum performance
             },
             CpuGovernor::PowerSave => {
                 // Set to minimum P-state
-                self.set_pstate(0)?;
+                if !self.p_states.is_empty() {
+                    self.set_pstate(0)?;
+                }
                 self.set_energy_perf_bias(15)?; // Maximum power saving
             },
             CpuGovernor::OnDemand => {
@@ -416,6 +448,9 @@ impl CpuFrequencyScaling {
         if bias > 15 {
             return Err("Invalid energy performance bias");
         }
+        if !self.energy_perf_bias_supported {
+            return Ok(());
+        }
         
         self.write_msr(MSR_IA32_ENERGY_PERF_BIAS, bias as u64)?;
         Ok(())
@@ -434,6 +469,14 @@ pub fn set_governor(governor: CpuGovernor) -> Result<(), &'static str> {
     CPU_FREQ.lock().set_governor(governor)
 }
 
+pub fn governor() -> CpuGovernor {
+    CPU_FREQ.lock().governor
+}
+
+pub fn status() -> CpuFreqStatus {
+    CPU_FREQ.lock().status()
+}
+
 pub fn set_frequency_limits(min_mhz: u32, max_mhz: u32) -> Result<(), &'static str> {
     let mut cpufreq = CPU_FREQ.lock();
     
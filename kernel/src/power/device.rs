@@ -1,17 +1,101 @@
-use alloc::vec::Vec;
-use alloc::string::String;
+//! Device power states, wake sources and runtime power management
+//!
+//! Every device in the driver tree is in a power state from D0, fully on,
+//! to D3cold, off. A system suspend takes the tree down from the leaves,
+//! each device after everything below it, and resume brings it back from
+//! the roots. The driver is told first through `Driver::suspend`, and may
+//! refuse, which abandons the suspend: the devices already down are
+//! brought back. A PCI function with the power management capability is
+//! then moved to the state through its PMCSR. Its header is saved before it
+//! leaves D0 and written back once it returns, since D3hot may lose it. A
+//! driver whose `resume` fails has its device unbound and probed again.
+//! Shutdown goes the same way, to D3cold, and nothing can refuse it.
+//!
+//! A wake source is a device that can wake the system: the keyboard, and a
+//! network adapter with Wake-on-LAN once its driver registers it. Those
+//! enabled are armed as they go down. Their driver is asked to keep
+//! watching, and a PCI function has PME enabled.
+//!
+//! Runtime power management puts a device in D3hot once it has been idle
+//! for its timeout: a USB host controller with nothing plugged in, and any
+//! device its driver enables it for. A device only goes down once
+//! everything below it has. `mark_busy` brings it back with its parents,
+//! and so does a PME from it. The power profile scales the timeouts, and
+//! Performance turns runtime power management off. Disks are not in the
+//! tree; the block layer spins them down after `power.disk_standby`.
+
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use spin::Mutex;
-use lazy_static::lazy_static;
+
+use super::WakeSourceType;
+use crate::boot::cmdline::Param;
+use crate::driver::probe::{self, Driver};
+use crate::driver::{Device, DeviceId, DeviceState, Ident, DEVICES};
+use crate::pcie::{
+    PciLocation, PCIE_CONTROLLER, PCI_CAPABILITIES_PTR, PCI_CAP_ID_PM, PCI_STATUS, PCI_STATUS_CAPABILITIES,
+};
 use crate::serial_println;
+use crate::time::clocksource::now_ns;
+use crate::time::hrtimer::sleep_ns;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+const NS_PER_MS: u64 = 1_000_000;
+// How often idle devices and PMEs are looked for
+const POLL_INTERVAL_NS: u64 = 100 * NS_PER_MS;
+const USB_HOST_TIMEOUT_MS: u64 = 2000;
+
+static DISK_STANDBY: Param<i64> =
+    Param::new("power.disk_standby", 600, "Seconds a disk is idle before it is spun down; 0 never");
+
+// The power management capability's registers, from its start
+const PCI_PM_PMC: u8 = 2;
+const PCI_PM_CTRL: u8 = 4;
+const PCI_PM_CAP_D1: u16 = 1 << 9;
+const PCI_PM_CAP_D2: u16 = 1 << 10;
+// One bit for each state PME can be signalled from, D0 to D3cold
+const PCI_PM_CAP_PME_SHIFT: u16 = 11;
+const PCI_PM_CTRL_STATE_MASK: u16 = 0x3;
+const PCI_PM_CTRL_PME_ENABLE: u16 = 1 << 8;
+const PCI_PM_CTRL_PME_STATUS: u16 = 1 << 15;
+// Recovery times the PCI Power Management spec allows
+const D3HOT_DELAY_NS: u64 = 10 * NS_PER_MS;
+const D2_DELAY_NS: u64 = 200_000;
+// The header's dwords, as saved across D3hot
+const HEADER_DWORDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DevicePowerState {
-    D0,      // Fully operational
-    D1,      // Light sleep
-    D2,      // Deeper sleep
-    D3Hot,   // Off but power maintained
-    D3Cold,  // Off and no power
+    D0,
+    D1,
+    D2,
+    D3Hot,
+    D3Cold,
+}
+
+impl DevicePowerState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DevicePowerState::D0 => "D0",
+            DevicePowerState::D1 => "D1",
+            DevicePowerState::D2 => "D2",
+            DevicePowerState::D3Hot => "D3hot",
+            DevicePowerState::D3Cold => "D3cold",
+        }
+    }
+
+    // The PMCSR's PowerState field. D3cold is D3hot with the power then
+    // cut, which only the platform can do.
+    fn pci_bits(self) -> u16 {
+        match self {
+            DevicePowerState::D0 => 0,
+            DevicePowerState::D1 => 1,
+            DevicePowerState::D2 => 2,
+            DevicePowerState::D3Hot | DevicePowerState::D3Cold => 3,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -21,428 +105,507 @@ pub enum RuntimePmPolicy {
     Aggressive,
 }
 
-#[derive(Debug)]
-pub struct DevicePower {
-    device_id: u64,
-    device_name: String,
-    current_state: DevicePowerState,
-    supported_states: Vec<DevicePowerState>,
-    device_type: DeviceType,
-    runtime_pm_enabled: bool,
-    idle_timeout_ms: u32,
-    last_activity: u64,
-    suspend_callback: Option<fn() -> Result<(), &'static str>>,
-    resume_callback: Option<fn() -> Result<(), &'static str>>,
-    power_consumption: DevicePowerConsumption,
+impl RuntimePmPolicy {
+    // An idle timeout as the policy has it, or None for never
+    fn scale(self, timeout_ms: u64) -> Option<u64> {
+        match self {
+            RuntimePmPolicy::Disabled => None,
+            RuntimePmPolicy::Auto => Some(timeout_ms),
+            RuntimePmPolicy::Aggressive => Some(timeout_ms / 4),
+        }
+    }
+}
+
+struct Runtime {
+    timeout_ms: u64,
+    last_busy_ns: u64,
+    /// Put down by runtime power management, not by a system suspend
+    suspended: bool,
+}
+
+struct Wake {
+    kind: WakeSourceType,
+    enabled: bool,
+    /// Set to wake the system as it went down
+    armed: bool,
+    /// Times it has signalled a wake
+    count: u64,
+}
+
+struct DevicePower {
+    state: DevicePowerState,
+    /// The PCI header as it was before the function left D0
+    saved: Option<[u32; HEADER_DWORDS]>,
+    runtime: Option<Runtime>,
+    wake: Option<Wake>,
+}
+
+impl Default for DevicePower {
+    fn default() -> Self {
+        Self { state: DevicePowerState::D0, saved: None, runtime: None, wake: None }
+    }
 }
 
+/// A device that can wake the system, for `powercfg`
 #[derive(Debug, Clone, Copy)]
-pub enum DeviceType {
-    PCI,
-    USB,
-    SATA,
-    Network,
-    Display,
-    Audio,
-    Input,
-    Storage,
-}
-
-#[derive(Debug, Default)]
-pub struct DevicePowerConsumption {
-    d0_mw: u32,
-    d1_mw: u32,
-    d2_mw: u32,
-    d3_mw: u32,
-}
-
-impl DevicePower {
-    pub fn new(device_id: u64, name: String, device_type: DeviceType) -> Self {
-        Self {
-            device_id,
-            device_name: name,
-            current_state: DevicePowerState::D0,
-            supported_states: vec![DevicePowerState::D0],
-            device_type,
-            runtime_pm_enabled: false,
-            idle_timeout_ms: 5000,
-            last_activity: 0,
-            suspend_callback: None,
-            resume_callback: None,
-            power_consumption: DevicePowerConsumption::default(),
-        }
+pub struct WakeSource {
+    pub device: DeviceId,
+    pub kind: WakeSourceType,
+    pub enabled: bool,
+    pub armed: bool,
+    pub count: u64,
+}
+
+// Taken after DEVICES when both are held, never before
+static POWER: Mutex<BTreeMap<DeviceId, DevicePower>> = Mutex::new(BTreeMap::new());
+static POLICY: Mutex<RuntimePmPolicy> = Mutex::new(RuntimePmPolicy::Auto);
+// What the last system suspend put down, in the order it did
+static SUSPENDED: Mutex<Vec<DeviceId>> = Mutex::new(Vec::new());
+static LAST_WAKE: Mutex<Option<DeviceId>> = Mutex::new(None);
+static NEXT_POLL_NS: AtomicU64 = AtomicU64::new(0);
+
+fn path(id: DeviceId) -> String {
+    DEVICES.lock().path(id)
+}
+
+fn driver_of(device: &Device) -> Option<&'static dyn Driver> {
+    device.driver.and_then(probe::driver_named)
+}
+
+fn pci_location(device: &Device) -> Option<PciLocation> {
+    match device.ident {
+        Ident::Pci { location, .. } => Some(location),
+        _ => None,
     }
-    
-    pub fn set_power_state(&mut self, state: DevicePowerState) -> Result<(), &'static str> {
-        if !self.supported_states.contains(&state) {
-            return Err("Unsupported power state");
-        }
-        
-        if state == self.current_state {
-            return Ok(());
-        }
-        
-        // Transition to new state
-        match (self.current_state, state) {
-            (DevicePowerState::D0, _) => {
-                // Going to sleep
-                if let Some(suspend) = self.suspend_callback {
-                    suspend()?;
-                }
-            },
-            (_, DevicePowerState::D0) => {
-                // Waking up
-                if let Some(resume) = self.resume_callback {
-                    resume()?;
-                }
-            },
-            _ => {}
+}
+
+// Where the function's power management capability is, if it has one
+fn pm_capability(location: PciLocation) -> Option<u8> {
+    let controller = PCIE_CONTROLLER.lock();
+    if controller.read16(location, PCI_STATUS) & PCI_STATUS_CAPABILITIES == 0 {
+        return None;
+    }
+    let mut offset = controller.read8(location, PCI_CAPABILITIES_PTR) & 0xFC;
+    // No more than 48 fit above the header, so a loop in the list ends
+    for _ in 0..48 {
+        if offset == 0 {
+            return None;
         }
-        
-        self.current_state = state;
-        serial_println!("Device {}: Power state changed to {:?}", 
-                       self.device_name, state);
-        
-        Ok(())
-    }
-    
-    pub fn enable_runtime_pm(&mut self, timeout_ms: u32) {
-        self.runtime_pm_enabled = true;
-        self.idle_timeout_ms = timeout_ms;
-        serial_println!("Device {}: Runtime PM enabled with {}ms timeout",
-                       self.device_name, timeout_ms);
-    }
-    
-    pub fn disable_runtime_pm(&mut self) {
-        self.runtime_pm_enabled = false;
-        // Wake device if sleeping
-        if self.current_state != DevicePowerState::D0 {
-            let _ = self.set_power_state(DevicePowerState::D0);
+        if controller.read8(location, offset) == PCI_CAP_ID_PM {
+            return Some(offset);
         }
+        offset = controller.read8(location, offset + 1) & 0xFC;
     }
-    
-    pub fn mark_active(&mut self) {
-        self.last_activity = Self::get_current_time();
-        
-        // Wake if sleeping
-        if self.runtime_pm_enabled && self.current_state != DevicePowerState::D0 {
-            let _ = self.set_power_state(DevicePowerState::D0);
-        }
+    None
+}
+
+// Whether the function has PME enabled and is signalling it
+fn pme_pending(location: PciLocation) -> bool {
+    let Some(cap) = pm_capability(location) else {
+        return false;
+    };
+    let control = PCIE_CONTROLLER.lock().read16(location, cap + PCI_PM_CTRL);
+    control & PCI_PM_CTRL_PME_ENABLE != 0 && control & PCI_PM_CTRL_PME_STATUS != 0
+}
+
+// Move a PCI function to `state`, with PME enabled if `wake` and the
+// function can signal it from there. Without the capability only the
+// driver's word for the state is kept.
+fn set_pci_state(
+    location: PciLocation,
+    power: &mut DevicePower,
+    state: DevicePowerState,
+    wake: bool,
+) -> Result<(), &'static str> {
+    let Some(cap) = pm_capability(location) else {
+        return Ok(());
+    };
+    let controller = PCIE_CONTROLLER.lock();
+    let capabilities = controller.read16(location, cap + PCI_PM_PMC);
+    let supported = match state {
+        DevicePowerState::D1 => capabilities & PCI_PM_CAP_D1 != 0,
+        DevicePowerState::D2 => capabilities & PCI_PM_CAP_D2 != 0,
+        _ => true,
+    };
+    if !supported {
+        return Err("power state not supported");
     }
-    
-    pub fn check_idle(&mut self) {
-        if !self.runtime_pm_enabled || self.current_state != DevicePowerState::D0 {
-            return;
-        }
-        
-        let current_time = Self::get_current_time();
-        let idle_time = current_time - self.last_activity;
-        
-        if idle_time > self.idle_timeout_ms as u64 {
-            // Put device to sleep
-            let target_state = self.get_auto_sleep_state();
-            let _ = self.set_power_state(target_state);
+    if state != DevicePowerState::D0 && power.saved.is_none() {
+        let mut saved = [0; HEADER_DWORDS];
+        for (index, dword) in saved.iter_mut().enumerate() {
+            *dword = controller.read32(location, index as u8 * 4);
         }
+        power.saved = Some(saved);
     }
-    
-    fn get_auto_sleep_state(&self) -> DevicePowerState {
-        // Choose appropriate sleep state based on device type
-        match self.device_type {
-            DeviceType::USB => DevicePowerState::D2,
-            DeviceType::Network => DevicePowerState::D1,
-            DeviceType::Display => DevicePowerState::D3Hot,
-            DeviceType::Storage => DevicePowerState::D1,
-            _ => DevicePowerState::D1,
-        }
+    let control = controller.read16(location, cap + PCI_PM_CTRL);
+    let from = control & PCI_PM_CTRL_STATE_MASK;
+    let pme = wake && capabilities >> (PCI_PM_CAP_PME_SHIFT + state as u16) & 1 != 0;
+    // PME_Status is cleared by writing it back
+    let mut control = control & !(PCI_PM_CTRL_STATE_MASK | PCI_PM_CTRL_PME_ENABLE) | PCI_PM_CTRL_PME_STATUS;
+    control |= state.pci_bits();
+    if pme {
+        control |= PCI_PM_CTRL_PME_ENABLE;
     }
-    
-    fn get_current_time() -> u64 {
-        // This would use a real timer
-        0
-    }
-    
-    pub fn get_power_consumption(&self) -> u32 {
-        match self.current_state {
-            DevicePowerState::D0 => self.power_consumption.d0_mw,
-            DevicePowerState::D1 => self.power_consumption.d1_mw,
-            DevicePowerState::D2 => self.power_consumption.d2_mw,
-            DevicePowerState::D3Hot | DevicePowerState::D3Cold => self.power_consumption.d3_mw,
+    controller.write16(location, cap + PCI_PM_CTRL, control);
+    drop(controller);
+
+    let to = state.pci_bits();
+    if from == 3 || to == 3 {
+        sleep_ns(D3HOT_DELAY_NS);
+    } else if from == 2 || to == 2 {
+        sleep_ns(D2_DELAY_NS);
+    }
+    if state == DevicePowerState::D0 {
+        if let Some(saved) = power.saved.take() {
+            let controller = PCIE_CONTROLLER.lock();
+            // Backwards, so that the command register, which turns decoding
+            // back on, is written last; the IDs are not written at all
+            for index in (1..HEADER_DWORDS).rev() {
+                let register = index as u8 * 4;
+                if controller.read32(location, register) != saved[index] {
+                    controller.write32(location, register, saved[index]);
+                }
+            }
         }
     }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct DevicePowerManager {
-    devices: BTreeMap<u64, DevicePower>,
-    runtime_pm_policy: RuntimePmPolicy,
-    suspended_devices: Vec<u64>,
-}
-
-impl DevicePowerManager {
-    pub fn new() -> Self {
-        Self {
-            devices: BTreeMap::new(),
-            runtime_pm_policy: RuntimePmPolicy::Auto,
-            suspended_devices: Vec::new(),
-        }
+// Take a device out of D0, its driver first; a device runtime power
+// management puts down is armed to wake itself
+fn suspend_device(id: DeviceId, state: DevicePowerState, runtime: bool) -> Result<(), &'static str> {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return Ok(());
+    };
+    let enabled = POWER.lock().get(&id).and_then(|power| power.wake.as_ref()).is_some_and(|wake| wake.enabled);
+    let wake = enabled || runtime;
+    let driver = driver_of(&device);
+    if let Some(driver) = driver {
+        driver.suspend(&device, state, wake)?;
     }
-    
-    pub fn register_device(&mut self, device: DevicePower) {
-        let device_id = device.device_id;
-        serial_println!("DevicePM: Registered device {} ({})", 
-                       device.device_name, device_id);
-        self.devices.insert(device_id, device);
-        
-        // Apply runtime PM policy
-        self.apply_runtime_pm_to_device(device_id);
-    }
-    
-    pub fn unregister_device(&mut self, device_id: u64) {
-        if let Some(device) = self.devices.remove(&device_id) {
-            serial_println!("DevicePM: Unregistered device {}", device.device_name);
+    let mut power = POWER.lock();
+    let record = power.entry(id).or_default();
+    if let Some(location) = pci_location(&device) {
+        if let Err(reason) = set_pci_state(location, record, state, wake) {
+            drop(power);
+            if let Some(driver) = driver {
+                let _ = driver.resume(&device);
+            }
+            return Err(reason);
         }
     }
-    
-    pub fn set_runtime_pm_policy(&mut self, policy: RuntimePmPolicy) {
-        self.runtime_pm_policy = policy;
-        
-        // Apply to all devices
-        let device_ids: Vec<u64> = self.devices.keys().cloned().collect();
-        for device_id in device_ids {
-            self.apply_runtime_pm_to_device(device_id);
+    record.state = state;
+    if let Some(wake) = record.wake.as_mut() {
+        wake.armed = enabled;
+    }
+    if let Some(record) = record.runtime.as_mut() {
+        record.suspended = runtime;
+    }
+    Ok(())
+}
+
+// Bring a device back to D0, returning whether it had signalled a wake
+fn resume_device(id: DeviceId) -> bool {
+    let Some(device) = DEVICES.lock().get(id).cloned() else {
+        return false;
+    };
+    let name = path(id);
+    let woke = {
+        let mut power = POWER.lock();
+        let record = power.entry(id).or_default();
+        if record.state == DevicePowerState::D0 {
+            return false;
         }
-        
-        serial_println!("DevicePM: Runtime PM policy set to {:?}", policy);
-    }
-    
-    fn apply_runtime_pm_to_device(&mut self, device_id: u64) {
-        if let Some(device) = self.devices.get_mut(&device_id) {
-            match self.runtime_pm_policy {
-                RuntimePmPolicy::Disabled => {
-                    device.disable_runtime_pm();
-                },
-                RuntimePmPolicy::Auto => {
-                    let timeout = match device.device_type {
-                        DeviceType::USB => 2000,
-                        DeviceType::Network => 5000,
-                        DeviceType::Display => 60000,
-                        _ => 10000,
-                    };
-                    device.enable_runtime_pm(timeout);
-                },
-                RuntimePmPolicy::Aggressive => {
-                    let timeout = match device.device_type {
-                        DeviceType::USB => 500,
-                        DeviceType::Network => 1000,
-                        DeviceType::Display => 30000,
-                        _ => 2000,
-                    };
-                    device.enable_runtime_pm(timeout);
-                },
+        let location = pci_location(&device);
+        let woke = location.is_some_and(pme_pending);
+        if let Some(location) = location {
+            if let Err(reason) = set_pci_state(location, record, DevicePowerState::D0, false) {
+                serial_println!("power: {} did not return to D0: {}", name, reason);
             }
         }
-    }
-    
-    pub fn suspend_all_devices(&mut self) -> Result<(), &'static str> {
-        serial_println!("DevicePM: Suspending all devices");
-        
-        self.suspended_devices.clear();
-        
-        // Suspend in reverse order (leaf devices first)
-        let device_ids: Vec<u64> = self.devices.keys().rev().cloned().collect();
-        
-        for device_id in device_ids {
-            if let Some(device) = self.devices.get_mut(&device_id) {
-                if device.current_state == DevicePowerState::D0 {
-                    device.set_power_state(DevicePowerState::D3Hot)?;
-                    self.suspended_devices.push(device_id);
-                }
-            }
+        record.state = DevicePowerState::D0;
+        if let Some(wake) = record.wake.as_mut() {
+            wake.armed = false;
+            wake.count += woke as u64;
         }
-        
-        Ok(())
-    }
-    
-    pub fn resume_all_devices(&mut self) -> Result<(), &'static str> {
-        serial_println!("DevicePM: Resuming all devices");
-        
-        // Resume in original order
-        for device_id in self.suspended_devices.clone() {
-            if let Some(device) = self.devices.get_mut(&device_id) {
-                device.set_power_state(DevicePowerState::D0)?;
-            }
+        if let Some(runtime) = record.runtime.as_mut() {
+            runtime.suspended = false;
+            runtime.last_busy_ns = now_ns();
         }
-        
-        self.suspended_devices.clear();
-        Ok(())
-    }
-    
-    pub fn update_idle_devices(&mut self) {
-        for device in self.devices.values_mut() {
-            device.check_idle();
+        woke
+    };
+    if let Some(driver) = driver_of(&device) {
+        if let Err(reason) = driver.resume(&device) {
+            serial_println!("power: {} did not resume: {}; probing it again", name, reason);
+            probe::unbind(id);
+            probe::probe_device(id);
         }
     }
-    
-    pub fn get_total_power_consumption(&self) -> u32 {
-        self.devices.values().map(|d| d.get_power_consumption()).sum()
-    }
+    woke
 }
 
-// Specific device implementations
+// Every device with power of its own, from the roots down, each before
+// those below it
+fn top_down() -> Vec<DeviceId> {
+    let devices = DEVICES.lock();
+    let mut order = Vec::new();
+    let mut stack: Vec<DeviceId> = devices.roots().map(|device| device.id).collect();
+    while let Some(id) = stack.pop() {
+        let Some(device) = devices.get(id) else {
+            continue;
+        };
+        if !matches!(device.ident, Ident::Root) && device.state != DeviceState::Disabled {
+            order.push(id);
+        }
+        stack.extend(device.children.iter().rev());
+    }
+    order
+}
 
-pub struct PCIDevicePower {
-    base: DevicePower,
-    config_space: [u8; 256],
+/// The device's power state
+pub fn state(id: DeviceId) -> DevicePowerState {
+    POWER.lock().get(&id).map_or(DevicePowerState::D0, |power| power.state)
 }
 
-impl PCIDevicePower {
-    pub fn new(bus: u8, device: u8, function: u8) -> Self {
-        let device_id = ((bus as u64) << 16) | ((device as u64) << 8) | (function as u64);
-        let name = format!("PCI {:02x}:{:02x}.{}", bus, device, function);
-        
-        let mut base = DevicePower::new(device_id, name, DeviceType::PCI);
-        base.supported_states = vec![
-            DevicePowerState::D0,
-            DevicePowerState::D1,
-            DevicePowerState::D2,
-            DevicePowerState::D3Hot,
-        ];
-        
-        Self {
-            base,
-            config_space: [0; 256],
+/// Put every device in D3hot for a system suspend, the leaves first. If a
+/// driver refuses, those already down are brought back.
+pub fn suspend_all_devices() -> Result<(), &'static str> {
+    let mut suspended = Vec::new();
+    for id in top_down().into_iter().rev() {
+        // What runtime power management has put down stays down
+        if state(id) != DevicePowerState::D0 {
+            continue;
+        }
+        if let Err(reason) = suspend_device(id, DevicePowerState::D3Hot, false) {
+            serial_println!("power: {} refused to suspend: {}", path(id), reason);
+            for id in suspended.into_iter().rev() {
+                resume_device(id);
+            }
+            return Err(reason);
         }
+        suspended.push(id);
     }
-    
-    pub fn save_config_space(&mut self) {
-        // Save PCI configuration space
-        serial_println!("PCI: Saving config space for {}", self.base.device_name);
-    }
-    
-    pub fn restore_config_space(&mut self) {
-        // Restore PCI configuration space
-        serial_println!("PCI: Restoring config space for {}", self.base.device_name);
-    }
-}
-
-pub struct USBDevicePower {
-    base: DevicePower,
-    port_number: u8,
-    selective_suspend: bool,
-}
-
-impl USBDevicePower {
-    pub fn new(port: u8) -> Self {
-        let device_id = 0x1000 | port as u64;
-        let name = format!("USB Port {}", port);
-        
-        let mut base = DevicePower::new(device_id, name, DeviceType::USB);
-        base.supported_states = vec![
-            DevicePowerState::D0,
-            DevicePowerState::D2,
-            DevicePowerState::D3Hot,
-        ];
-        
-        Self {
-            base,
-            port_number: port,
-            selective_suspend: true,
+    serial_println!("power: {} devices suspended", suspended.len());
+    *SUSPENDED.lock() = suspended;
+    Ok(())
+}
+
+/// Bring back what `suspend_all_devices` put down, each device before those
+/// below it, and note which signalled the wake
+pub fn resume_all_devices() -> Result<(), &'static str> {
+    let suspended = core::mem::take(&mut *SUSPENDED.lock());
+    let mut woken_by = None;
+    for id in suspended.into_iter().rev() {
+        if resume_device(id) && woken_by.is_none() {
+            woken_by = Some(id);
         }
     }
-    
-    pub fn enable_selective_suspend(&mut self) {
-        self.selective_suspend = true;
-        self.base.enable_runtime_pm(1000);
-    }
-}
-
-pub struct SATADevicePower {
-    base: DevicePower,
-    port_number: u8,
-    link_power_management: bool,
-    spin_down_timeout: u32,
-}
-
-impl SATADevicePower {
-    pub fn new(port: u8) -> Self {
-        let device_id = 0x2000 | port as u64;
-        let name = format!("SATA Port {}", port);
-        
-        let mut base = DevicePower::new(device_id, name, DeviceType::SATA);
-        base.supported_states = vec![
-            DevicePowerState::D0,
-            DevicePowerState::D1,
-            DevicePowerState::D3Hot,
-        ];
-        
-        Self {
-            base,
-            port_number: port,
-            link_power_management: true,
-            spin_down_timeout: 600000, // 10 minutes
+    *LAST_WAKE.lock() = woken_by;
+    Ok(())
+}
+
+/// Power every device down before the system is turned off, the leaves
+/// first. Drivers cannot refuse; their errors are logged. Enabled wake
+/// sources are left armed, for Wake-on-LAN from soft off.
+pub fn shutdown_all_devices() {
+    for id in top_down().into_iter().rev() {
+        if state(id) != DevicePowerState::D0 {
+            continue;
+        }
+        if let Err(reason) = suspend_device(id, DevicePowerState::D3Cold, false) {
+            serial_println!("power: {} did not power down: {}", path(id), reason);
         }
     }
-    
-    pub fn set_spin_down_timeout(&mut self, timeout_ms: u32) {
-        self.spin_down_timeout = timeout_ms;
-        serial_println!("SATA: Spin-down timeout set to {}ms", timeout_ms);
+}
+
+/// Let a device wake the system, as its driver finds it can. Registering it
+/// again keeps whether it was enabled.
+pub fn register_wake_source(id: DeviceId, kind: WakeSourceType, enabled: bool) {
+    let mut power = POWER.lock();
+    power.entry(id).or_default().wake.get_or_insert(Wake { kind, enabled, armed: false, count: 0 });
+}
+
+/// Allow or forbid a wake source to wake the system
+pub fn enable_wake(id: DeviceId, enable: bool) -> Result<(), &'static str> {
+    match POWER.lock().get_mut(&id).and_then(|power| power.wake.as_mut()) {
+        Some(wake) => {
+            wake.enabled = enable;
+            Ok(())
+        }
+        None => Err("device cannot wake the system"),
     }
 }
 
-lazy_static! {
-    static ref DEVICE_PM: Mutex<DevicePowerManager> = Mutex::new(DevicePowerManager::new());
+pub fn wake_sources() -> Vec<WakeSource> {
+    POWER
+        .lock()
+        .iter()
+        .filter_map(|(id, power)| {
+            let wake = power.wake.as_ref()?;
+            Some(WakeSource {
+                device: *id,
+                kind: wake.kind,
+                enabled: wake.enabled,
+                armed: wake.armed,
+                count: wake.count,
+            })
+        })
+        .collect()
 }
 
-pub fn init() -> Result<(), &'static str> {
-    serial_println!("DevicePM: Initializing device power management");
-    
-    // Register sample devices for testing
-    register_sample_devices();
-    
-    Ok(())
+/// The device that signalled the last wake from suspend, if one was seen to
+pub fn last_wake() -> Option<DeviceId> {
+    *LAST_WAKE.lock()
 }
 
-fn register_sample_devices() {
-    let mut pm = DEVICE_PM.lock();
-    
-    // Register PCI devices
-    for bus in 0..2 {
-        for device in 0..4 {
-            let pci = PCIDevicePower::new(bus, device, 0);
-            pm.register_device(pci.base);
-        }
+/// Let runtime power management put the device in D3hot once it has been
+/// idle for `timeout_ms`, as the policy scales it
+pub fn enable_runtime_pm(id: DeviceId, timeout_ms: u64) {
+    let mut power = POWER.lock();
+    power.entry(id).or_default().runtime = Some(Runtime { timeout_ms, last_busy_ns: now_ns(), suspended: false });
+}
+
+/// Stop runtime power management of the device, bringing it back if it is
+/// down
+pub fn disable_runtime_pm(id: DeviceId) {
+    wake_up(id);
+    if let Some(power) = POWER.lock().get_mut(&id) {
+        power.runtime = None;
     }
-    
-    // Register USB ports
-    for port in 0..4 {
-        let usb = USBDevicePower::new(port);
-        pm.register_device(usb.base);
+}
+
+/// The device is in use: bring it back if it is down, and start its idle
+/// time again
+pub fn mark_busy(id: DeviceId) {
+    wake_up(id);
+    if let Some(runtime) = POWER.lock().get_mut(&id).and_then(|power| power.runtime.as_mut()) {
+        runtime.last_busy_ns = now_ns();
     }
-    
-    // Register SATA ports
-    for port in 0..2 {
-        let sata = SATADevicePower::new(port);
-        pm.register_device(sata.base);
+}
+
+// Bring a runtime-suspended device back, and whatever it is behind first
+fn wake_up(id: DeviceId) {
+    let chain: Vec<DeviceId> = {
+        let devices = DEVICES.lock();
+        core::iter::successors(Some(id), |id| devices.get(*id).and_then(|device| device.parent)).collect()
+    };
+    for id in chain.into_iter().rev() {
+        let suspended = POWER.lock().get(&id).and_then(|power| power.runtime.as_ref()).is_some_and(|r| r.suspended);
+        if suspended {
+            resume_device(id);
+        }
     }
 }
 
+/// Change how soon idle devices are put down; disabling brings back those
+/// that are
 pub fn set_runtime_pm_policy(policy: RuntimePmPolicy) -> Result<(), &'static str> {
-    DEVICE_PM.lock().set_runtime_pm_policy(policy);
+    *POLICY.lock() = policy;
+    if policy == RuntimePmPolicy::Disabled {
+        let down: Vec<DeviceId> = POWER
+            .lock()
+            .iter()
+            .filter(|(_, power)| power.runtime.as_ref().is_some_and(|runtime| runtime.suspended))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in down {
+            mark_busy(id);
+        }
+    }
     Ok(())
 }
 
-pub fn suspend_all_devices() -> Result<(), &'static str> {
-    DEVICE_PM.lock().suspend_all_devices()
+// Runtime-suspended functions that signal PME want to be back in D0
+fn runtime_pme() {
+    let down: Vec<DeviceId> = POWER
+        .lock()
+        .iter()
+        .filter(|(_, power)| power.runtime.as_ref().is_some_and(|runtime| runtime.suspended))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in down {
+        let location = DEVICES.lock().get(id).and_then(pci_location);
+        if location.is_some_and(pme_pending) {
+            serial_println!("power: {} signalled PME", path(id));
+            mark_busy(id);
+        }
+    }
 }
 
-pub fn resume_all_devices() -> Result<(), &'static str> {
-    DEVICE_PM.lock().resume_all_devices()
+// Put down the devices idle past their timeout whose children are all down
+fn runtime_idle(now: u64, policy: RuntimePmPolicy) {
+    let idle: Vec<DeviceId> = POWER
+        .lock()
+        .iter()
+        .filter(|(_, power)| power.state == DevicePowerState::D0)
+        .filter_map(|(id, power)| {
+            let runtime = power.runtime.as_ref()?;
+            let timeout_ns = policy.scale(runtime.timeout_ms)? * NS_PER_MS;
+            (now.saturating_sub(runtime.last_busy_ns) >= timeout_ns).then_some(*id)
+        })
+        .collect();
+    for id in idle {
+        let children: Vec<DeviceId> = {
+            let devices = DEVICES.lock();
+            devices.children(id).filter(|child| child.state != DeviceState::Disabled).map(|child| child.id).collect()
+        };
+        if children.into_iter().any(|child| state(child) == DevicePowerState::D0) {
+            continue;
+        }
+        if let Err(reason) = suspend_device(id, DevicePowerState::D3Hot, true) {
+            serial_println!("power: {} stays in D0: {}", path(id), reason);
+            // Asked again once it has been idle as long once more
+            if let Some(runtime) = POWER.lock().get_mut(&id).and_then(|power| power.runtime.as_mut()) {
+                runtime.last_busy_ns = now;
+            }
+        }
+    }
 }
 
-pub fn update_idle_devices() {
-    DEVICE_PM.lock().update_idle_devices();
+/// Runtime power management, from the main loop: bring back the devices
+/// that signal PME, and put down those idle too long, disks included
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL_NS.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL_NS.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    let policy = *POLICY.lock();
+    runtime_pme();
+    runtime_idle(now, policy);
+    let standby_ms = DISK_STANDBY.get().max(0) as u64 * 1000;
+    if let Some(timeout_ms) = policy.scale(standby_ms).filter(|_| standby_ms > 0) {
+        crate::drivers::block::standby_idle(timeout_ms * NS_PER_MS);
+    }
+}
+
+/// A device has left the tree
+pub fn forget(id: DeviceId) {
+    POWER.lock().remove(&id);
 }
 
-pub fn get_total_device_power() -> u32 {
-    DEVICE_PM.lock().get_total_power_consumption()
-}
\ No newline at end of file
+/// Register the wake sources and runtime power management every PC has,
+/// once the device tree is built
+pub fn init() {
+    let (keyboard, usb_hosts) = {
+        let devices = DEVICES.lock();
+        let keyboard = devices.iter().find(|device| matches!(device.ident, Ident::Platform { name: "i8042", .. }));
+        let usb_hosts: Vec<DeviceId> = devices
+            .iter()
+            .filter(|device| matches!(device.ident, Ident::Pci { class: 0x0C, subclass: 0x03, .. }))
+            .map(|device| device.id)
+            .collect();
+        (keyboard.map(|device| device.id), usb_hosts)
+    };
+    if let Some(keyboard) = keyboard {
+        register_wake_source(keyboard, WakeSourceType::Keyboard, true);
+    }
+    for host in &usb_hosts {
+        enable_runtime_pm(*host, USB_HOST_TIMEOUT_MS);
+    }
+    serial_println!(
+        "power: {} USB hosts under runtime power management, disks spin down after {} s",
+        usb_hosts.len(),
+        DISK_STANDBY.get()
+    );
+}
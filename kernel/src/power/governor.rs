@@ -12,6 +12,31 @@ pub enum CpuGovernor {
     Schedutil,
 }
 
+impl CpuGovernor {
+    pub const ALL: [CpuGovernor; 5] = [
+        CpuGovernor::Performance,
+        CpuGovernor::PowerSave,
+        CpuGovernor::OnDemand,
+        CpuGovernor::Conservative,
+        CpuGovernor::Schedutil,
+    ];
+
+    /// The name Linux gives the governor in scaling_governor
+    pub fn name(self) -> &'static str {
+        match self {
+            CpuGovernor::Performance => "performance",
+            CpuGovernor::PowerSave => "powersave",
+            CpuGovernor::OnDemand => "ondemand",
+            CpuGovernor::Conservative => "conservative",
+            CpuGovernor::Schedutil => "schedutil",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|governor| governor.name().eq_ignore_ascii_case(name))
+    }
+}
+
 #[derive(Debug)]
 pub struct GovernorState {
     governor: CpuGovernor,
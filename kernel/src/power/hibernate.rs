@@ -425,7 +425,11 @@ lazy_static! {
 
 pub fn init() -> Result<(), &'static str> {
     serial_println!("Hibernate: Initializing S4 hibernation support");
-    
+    available()
+}
+
+/// Whether the machine can hibernate, and if not why
+pub fn available() -> Result<(), &'static str> {
     // Check if hibernation is supported
     if !is_hibernation_supported() {
         return Err("Hibernation not supported");
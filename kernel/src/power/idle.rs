@@ -0,0 +1,426 @@
+//! CPU idle
+//!
+//! An idle CPU waits in the deepest C-state worth entering before its next
+//! wakeup. The states come from the firmware's _CST when ACPI describes
+//! them, from CPUID's MWAIT leaf on bare metal, and otherwise are just HLT;
+//! a POLL state that spins for very short waits sits below all of them.
+//! The choice weighs the time to the next high-resolution timer and the
+//! CPU's recent idle periods against each state's target residency, under
+//! an exit latency limit.
+//!
+//! A halted CPU needs an interrupt to wake it. The boot CPU idles with
+//! interrupts off, so it halts only if the HPET comparator has been seen to
+//! deliver its MSI: the comparator is armed for the wakeup and the legacy
+//! PIC lines are masked while interrupts are briefly let in, bar those of
+//! devices that asked to wake it, such as the serial ports. If the boot
+//! CPU takes interrupts anyway, its periodic tick is stopped for the
+//! duration (tickless idle) and the ticks missed are counted on waking.
+//! With no way to be woken in time, a bounded wait is polled.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::acpi::cst::{self, Entry};
+use crate::smp::MAX_CPUS;
+use crate::time::clocksource::{self, now_ns, ClockSource};
+use crate::time::{hpet, hrtimer};
+
+/// POLL plus up to seven C-states
+pub const MAX_STATES: usize = 8;
+
+const NAMES: [&str; MAX_STATES] = ["POLL", "C1", "C2", "C3", "C4", "C5", "C6", "C7"];
+
+// CPUID.1 ECX
+const CPUID_MONITOR: u32 = 1 << 3;
+const CPUID_HYPERVISOR: u32 = 1 << 31;
+// CPUID.5 ECX: EDX enumerates the MWAIT sub-states
+const CPUID_MWAIT_ENUMERATED: u32 = 1 << 0;
+// Rough exit latencies of MWAIT C-states, for parts whose firmware does
+// not give them
+const MWAIT_LATENCY_US: [u32; MAX_STATES] = [0, 2, 10, 40, 100, 150, 250, 500];
+
+// Stay asleep at most this long, so the boot CPU's watchdog and clocksource
+// bookkeeping still get to run
+const MAX_IDLE_NS: u64 = 1_000_000_000;
+// Shortest wait predicted, so POLL never collapses to no wait at all
+const MIN_PREDICTION_NS: u64 = 1_000;
+
+// Local APIC interrupt request register, eight 32-bit words 16 bytes apart
+const APIC_BASE: u64 = 0xFEE0_0000;
+const APIC_IRR: u64 = 0x200;
+
+// Legacy PIC data ports, which hold the line masks
+const PIC1_DATA: u16 = 0x21;
+const PIC2_DATA: u16 = 0xA1;
+// Master line the slave PIC is chained to
+const PIC_CASCADE: u8 = 1 << 2;
+
+/// How a state is entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Spin with PAUSE
+    Poll,
+    Halt,
+    Mwait(u32),
+    /// Read a P_LVLx port
+    IoPort(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IdleState {
+    pub name: &'static str,
+    pub method: Method,
+    pub exit_latency_us: u32,
+    /// Shortest stay for which entering the state saves power
+    pub target_residency_us: u32,
+    /// Power drawn in the state, 0 if unknown
+    pub power_mw: u32,
+}
+
+impl IdleState {
+    const POLL: IdleState =
+        IdleState { name: "POLL", method: Method::Poll, exit_latency_us: 0, target_residency_us: 0, power_mw: 0 };
+
+    // Target residency is twice the exit latency, as Linux takes it for
+    // ACPI states
+    fn new(kind: u8, method: Method, exit_latency_us: u32, power_mw: u32) -> Self {
+        IdleState {
+            name: NAMES[(kind as usize).min(MAX_STATES - 1)],
+            method,
+            exit_latency_us,
+            target_residency_us: exit_latency_us.saturating_mul(2),
+            power_mw,
+        }
+    }
+}
+
+struct CpuStats {
+    usage: [AtomicU64; MAX_STATES],
+    time_ns: [AtomicU64; MAX_STATES],
+    idle_ns: AtomicU64,
+    tickless: AtomicU64,
+    // Running average of recent idle periods
+    average_ns: AtomicU64,
+    // Line MWAIT monitors
+    monitor: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        CpuStats {
+            usage: [const { AtomicU64::new(0) }; MAX_STATES],
+            time_ns: [const { AtomicU64::new(0) }; MAX_STATES],
+            idle_ns: AtomicU64::new(0),
+            tickless: AtomicU64::new(0),
+            average_ns: AtomicU64::new(MAX_IDLE_NS),
+            monitor: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Idle time of one CPU, per state in the order of `states()`
+#[derive(Debug, Clone)]
+pub struct IdleStats {
+    pub usage: Vec<u64>,
+    pub time_ns: Vec<u64>,
+    pub idle_ns: u64,
+    /// Idle periods spent with the tick stopped
+    pub tickless: u64,
+}
+
+static STATES: Once<Vec<IdleState>> = Once::new();
+static STATS: [CpuStats; MAX_CPUS] = [const { CpuStats::new() }; MAX_CPUS];
+static LATENCY_LIMIT_US: AtomicU32 = AtomicU32::new(u32::MAX);
+static HPET_WAKES: AtomicBool = AtomicBool::new(false);
+// Legacy IRQ lines left open while the boot CPU halts, one bit per line
+static WAKE_IRQS: AtomicU16 = AtomicU16::new(0);
+
+fn monitor_supported() -> bool {
+    core::arch::x86_64::__cpuid(1).ecx & CPUID_MONITOR != 0
+}
+
+// States the firmware describes, shallowest first
+fn firmware_states() -> Option<Vec<IdleState>> {
+    let mwait = monitor_supported();
+    let mut states: Vec<IdleState> = cst::find()?
+        .into_iter()
+        .filter_map(|state| {
+            let method = match state.entry {
+                Entry::Halt => Method::Halt,
+                Entry::Mwait { hint, .. } if mwait => Method::Mwait(hint),
+                // C3 through a port needs bus master arbitration and cache
+                // flushing, which are not done
+                Entry::IoPort(port) if state.kind == 2 => Method::IoPort(port),
+                _ => return None,
+            };
+            Some(IdleState::new(state.kind, method, state.latency_us, state.power_mw))
+        })
+        .collect();
+    states.sort_by_key(|state| state.exit_latency_us);
+    states.truncate(MAX_STATES - 1);
+    (!states.is_empty()).then_some(states)
+}
+
+// MWAIT C-states CPUID enumerates, each entered at its first sub-state. A
+// hypervisor usually traps MWAIT and carries on as if it were a NOP, so
+// guests halt instead.
+fn cpuid_states() -> Option<Vec<IdleState>> {
+    let leaf1 = core::arch::x86_64::__cpuid(1);
+    if leaf1.ecx & CPUID_MONITOR == 0
+        || leaf1.ecx & CPUID_HYPERVISOR != 0
+        || core::arch::x86_64::__cpuid(0).eax < 5
+    {
+        return None;
+    }
+    let leaf5 = core::arch::x86_64::__cpuid(5);
+    if leaf5.ecx & CPUID_MWAIT_ENUMERATED == 0 {
+        return None;
+    }
+    let states: Vec<IdleState> = (1..MAX_STATES)
+        .filter(|&n| (leaf5.edx >> (4 * n)) & 0xF != 0)
+        .map(|n| IdleState::new(n as u8, Method::Mwait(((n - 1) as u32) << 4), MWAIT_LATENCY_US[n], 0))
+        .collect();
+    (!states.is_empty()).then_some(states)
+}
+
+// Mask every legacy PIC line but the enabled wakeup sources, returning the
+// masks to restore
+fn mask_legacy() -> (u8, u8) {
+    let wake = WAKE_IRQS.load(Ordering::Relaxed);
+    unsafe {
+        let (mut master, mut slave) = (Port::<u8>::new(PIC1_DATA), Port::<u8>::new(PIC2_DATA));
+        let masks = (master.read(), slave.read());
+        let slave_open = (wake >> 8) as u8 & !masks.1;
+        let master_open = wake as u8 & !masks.0 | if slave_open != 0 { PIC_CASCADE } else { 0 };
+        master.write(!master_open);
+        slave.write(!slave_open);
+        masks
+    }
+}
+
+fn restore_legacy((master, slave): (u8, u8)) {
+    unsafe {
+        Port::<u8>::new(PIC1_DATA).write(master);
+        Port::<u8>::new(PIC2_DATA).write(slave);
+    }
+}
+
+fn wake(_: u64) {}
+
+// Whether the HPET comparator's MSI reaches this CPU, so it can be trusted
+// to end a halt: arm it, watch for its vector in the local APIC's IRR, then
+// let the handler take it with nothing else let in
+fn hpet_wakes() -> bool {
+    if !hpet::has_event_timer() {
+        return false;
+    }
+    let vector = hpet::HPET_VECTOR as u64;
+    let irr = (APIC_BASE + APIC_IRR + 0x10 * (vector / 32)) as *const u32;
+    let bit = 1u32 << (vector % 32);
+
+    interrupts::without_interrupts(|| {
+        let id = hrtimer::start_after(1_000_000, wake, 0);
+        let give_up = now_ns() + 20_000_000;
+        let mut pending = false;
+        while !pending && now_ns() < give_up {
+            pending = unsafe { irr.read_volatile() } & bit != 0;
+            core::hint::spin_loop();
+        }
+        if pending {
+            let masks = mask_legacy();
+            interrupts::enable();
+            x86_64::instructions::nop();
+            interrupts::disable();
+            restore_legacy(masks);
+        } else {
+            hrtimer::cancel(id);
+        }
+        pending
+    })
+}
+
+/// Work out the idle states and how the boot CPU can be woken
+pub fn init() {
+    let mut states = vec![IdleState::POLL];
+    states.extend(firmware_states().or_else(cpuid_states).unwrap_or_else(|| vec![IdleState::new(1, Method::Halt, 1, 0)]));
+    let states = STATES.call_once(|| states);
+    HPET_WAKES.store(hpet_wakes(), Ordering::Relaxed);
+
+    crate::serial_println!(
+        "[IDLE] {} states ({}), {}",
+        states.len(),
+        states.iter().map(|state| state.name).collect::<Vec<_>>().join(" "),
+        if HPET_WAKES.load(Ordering::Relaxed) { "HPET wakeups" } else { "no timed wakeups" }
+    );
+}
+
+fn states_or_poll() -> &'static [IdleState] {
+    STATES.get().map(Vec::as_slice).unwrap_or(core::slice::from_ref(&IdleState::POLL))
+}
+
+// Deepest state that pays off within `predicted_ns` and wakes fast enough
+fn select(states: &[IdleState], predicted_ns: u64) -> usize {
+    let limit = LATENCY_LIMIT_US.load(Ordering::Relaxed);
+    states
+        .iter()
+        .rposition(|state| state.target_residency_us as u64 * 1_000 <= predicted_ns && state.exit_latency_us <= limit)
+        .unwrap_or(0)
+}
+
+// Enter a sleeping state, taking interrupts for the duration if `enable`.
+// STI holds interrupts off until after the next instruction, so one that
+// arrives just before the halt still ends it.
+fn sleep(method: Method, enable: bool, monitor: &AtomicU64) {
+    match method {
+        Method::Poll => core::hint::spin_loop(),
+        Method::Halt if enable => {
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+        Method::Halt => x86_64::instructions::hlt(),
+        Method::Mwait(hint) => unsafe {
+            core::arch::asm!("monitor", in("rax") monitor.as_ptr(), in("ecx") 0u32, in("edx") 0u32);
+            if enable {
+                core::arch::asm!("sti", "mwait", "cli", in("eax") hint, in("ecx") 0u32);
+            } else {
+                core::arch::asm!("mwait", in("eax") hint, in("ecx") 0u32);
+            }
+        },
+        // The chipset holds the CPU in the state until an interrupt is
+        // pending, whether or not it is taken
+        Method::IoPort(port) => unsafe {
+            Port::<u8>::new(port).read();
+            if enable {
+                interrupts::enable();
+                x86_64::instructions::nop();
+                interrupts::disable();
+            }
+        },
+    }
+}
+
+/// Idle for at most `budget_ns`, or until an interrupt if `budget_ns` is
+/// `u64::MAX`. Returns early on any wakeup; the caller checks for work and
+/// calls again.
+pub fn enter(budget_ns: u64) {
+    let cpu = (crate::smp::current_cpu_id() as usize).min(MAX_CPUS - 1);
+    let interrupts_on = interrupts::are_enabled();
+    // With the tick as the only clock and no interrupts to advance it, time
+    // stands still and a bounded wait would never end
+    if !interrupts_on && budget_ns != u64::MAX && clocksource::current() == ClockSource::Jiffies {
+        core::hint::spin_loop();
+        return;
+    }
+
+    let start = now_ns();
+    // The HPET only interrupts the boot CPU, so only it minds the timers
+    let boot_cpu = cpu == 0;
+    let next_timer = if boot_cpu { hrtimer::next_expiry() } else { None };
+    let deadline = next_timer.map_or(start.saturating_add(budget_ns), |next| {
+        next.min(start.saturating_add(budget_ns))
+    });
+    if deadline <= start {
+        if boot_cpu {
+            hrtimer::run_expired();
+        }
+        return;
+    }
+
+    let stats = &STATS[cpu];
+    let hpet_wake = boot_cpu && HPET_WAKES.load(Ordering::Relaxed);
+    let bounded = deadline != u64::MAX;
+    let can_sleep = interrupts_on || hpet_wake || !bounded;
+    let predicted = if can_sleep {
+        (deadline - start).min(stats.average_ns.load(Ordering::Relaxed).saturating_mul(2)).max(MIN_PREDICTION_NS)
+    } else {
+        deadline - start
+    };
+    let states = states_or_poll();
+    let index = if can_sleep { select(states, predicted) } else { 0 };
+    let state = &states[index];
+
+    if state.method == Method::Poll {
+        let until = start.saturating_add(predicted).min(deadline);
+        while now_ns() < until {
+            core::hint::spin_loop();
+        }
+    } else {
+        interrupts::disable();
+        let wake_timer = hpet_wake.then(|| hrtimer::start(deadline.min(start + MAX_IDLE_NS), wake, 0));
+        // Stop the tick while the comparator stands in for it
+        let tickless = interrupts_on && hpet_wake && clocksource::current() != ClockSource::Jiffies;
+        if tickless {
+            crate::timer::TIMER.lock().suspend_tick();
+            stats.tickless.fetch_add(1, Ordering::Relaxed);
+        }
+        // Interrupts are let in only for the HPET and wakeup sources when the
+        // caller had them off
+        let masks = (!interrupts_on && hpet_wake).then(mask_legacy);
+
+        sleep(state.method, interrupts_on || hpet_wake, &stats.monitor);
+
+        if let Some(masks) = masks {
+            restore_legacy(masks);
+        }
+        if tickless {
+            crate::timer::TIMER.lock().resume_tick();
+        }
+        if let Some(id) = wake_timer {
+            hrtimer::cancel(id);
+        }
+        if interrupts_on {
+            interrupts::enable();
+        }
+    }
+
+    let residency = now_ns().saturating_sub(start);
+    stats.usage[index].fetch_add(1, Ordering::Relaxed);
+    stats.time_ns[index].fetch_add(residency, Ordering::Relaxed);
+    stats.idle_ns.fetch_add(residency, Ordering::Relaxed);
+    let average = stats.average_ns.load(Ordering::Relaxed);
+    stats.average_ns.store((average * 7 + residency.min(MAX_IDLE_NS)) / 8, Ordering::Relaxed);
+
+    if boot_cpu {
+        hrtimer::run_expired();
+    }
+}
+
+pub fn states() -> Vec<IdleState> {
+    states_or_poll().to_vec()
+}
+
+pub fn stats(cpu: usize) -> IdleStats {
+    let stats = &STATS[cpu.min(MAX_CPUS - 1)];
+    let count = states_or_poll().len();
+    IdleStats {
+        usage: stats.usage[..count].iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+        time_ns: stats.time_ns[..count].iter().map(|n| n.load(Ordering::Relaxed)).collect(),
+        idle_ns: stats.idle_ns.load(Ordering::Relaxed),
+        tickless: stats.tickless.load(Ordering::Relaxed),
+    }
+}
+
+/// Deepest exit latency allowed, in microseconds
+pub fn latency_limit() -> u32 {
+    LATENCY_LIMIT_US.load(Ordering::Relaxed)
+}
+
+pub fn set_latency_limit(us: u32) {
+    LATENCY_LIMIT_US.store(us, Ordering::Relaxed);
+}
+
+/// Whether the boot CPU can sleep with interrupts off, woken by the HPET
+pub fn hpet_wakeups() -> bool {
+    HPET_WAKES.load(Ordering::Relaxed)
+}
+
+/// Let legacy IRQ `irq` end the boot CPU's idle halt even though it runs
+/// with interrupts off; the line's handler then runs in the idle loop
+pub fn allow_wakeup(irq: u8) {
+    WAKE_IRQS.fetch_or(1 << irq, Ordering::Relaxed);
+}
@@ -5,6 +5,9 @@ pub mod device;
 pub mod battery;
 pub mod profile;
 pub mod governor;
+pub mod idle;
+pub mod rfkill;
+pub mod shutdown;
 
 #[cfg(test)]
 mod test;
@@ -15,6 +18,8 @@ use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::serial_println;
 
+pub use device::DevicePowerState;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PowerState {
     S0,     // Working
@@ -25,15 +30,6 @@ pub enum PowerState {
     S5,     // Soft off
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum DevicePowerState {
-    D0,     // Fully on
-    D1,     // Light sleep
-    D2,     // Deep sleep
-    D3Hot,  // Off but power maintained
-    D3Cold, // Off and no power
-}
-
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PowerProfile {
     Performance,
@@ -63,7 +59,7 @@ pub struct WakeSource {
     pub source_type: WakeSourceType,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WakeSourceType {
     PowerButton,
     Keyboard,
@@ -74,6 +70,20 @@ pub enum WakeSourceType {
     LidSwitch,
 }
 
+impl WakeSourceType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WakeSourceType::PowerButton => "power button",
+            WakeSourceType::Keyboard => "keyboard",
+            WakeSourceType::Mouse => "mouse",
+            WakeSourceType::Network => "network",
+            WakeSourceType::RTC => "RTC alarm",
+            WakeSourceType::USB => "USB",
+            WakeSourceType::LidSwitch => "lid switch",
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct PowerConsumption {
     pub cpu_power_mw: u32,
@@ -116,9 +126,6 @@ impl PowerManagementSystem {
         suspend::init()?;
         hibernate::init()?;
         
-        // Initialize device power management
-        device::init()?;
-        
         // Check for battery
         if battery::init().is_ok() {
             self.battery_present = true;
@@ -153,7 +160,8 @@ impl PowerManagementSystem {
     }
 
     fn init_wake_sources(&mut self) -> Result<(), &'static str> {
-        // Initialize default wake sources
+        // Wake sources with no device of their own; devices register theirs
+        // with `device::register_wake_source`
         self.wake_sources.push(WakeSource {
             id: 0,
             name: String::from("Power Button"),
@@ -163,13 +171,6 @@ impl PowerManagementSystem {
         
         self.wake_sources.push(WakeSource {
             id: 1,
-            name: String::from("Keyboard"),
-            enabled: true,
-            source_type: WakeSourceType::Keyboard,
-        });
-        
-        self.wake_sources.push(WakeSource {
-            id: 2,
             name: String::from("RTC Alarm"),
             enabled: false,
             source_type: WakeSourceType::RTC,
@@ -257,6 +258,24 @@ impl PowerManagementSystem {
         unreachable!()
     }
 
+    pub fn set_hibernate_enabled(&mut self, enable: bool) -> Result<(), &'static str> {
+        if enable {
+            hibernate::available()?;
+        }
+        self.hibernate_enabled = enable;
+        serial_println!("Power: Hibernation {}", if enable { "enabled" } else { "disabled" });
+        Ok(())
+    }
+
+    /// Choose the CPU governor by hand, leaving the profile as Custom
+    pub fn set_cpu_governor(&mut self, governor: governor::CpuGovernor) -> Result<(), &'static str> {
+        cpufreq::set_governor(governor)?;
+        governor::set_active_governor(governor)?;
+        self.cpu_governor = governor;
+        self.current_profile = PowerProfile::Custom;
+        Ok(())
+    }
+
     pub fn enable_wake_source(&mut self, source_type: WakeSourceType, enable: bool) {
         for source in &mut self.wake_sources {
             if source.source_type == source_type {
@@ -324,6 +343,22 @@ pub fn get_power_state() -> PowerState {
     POWER_MGMT.lock().current_state
 }
 
+pub fn set_hibernate_enabled(enable: bool) -> Result<(), &'static str> {
+    POWER_MGMT.lock().set_hibernate_enabled(enable)
+}
+
+pub fn hibernate_enabled() -> bool {
+    POWER_MGMT.lock().hibernate_enabled
+}
+
+pub fn suspend_enabled() -> bool {
+    POWER_MGMT.lock().suspend_enabled
+}
+
+pub fn set_cpu_governor(governor: governor::CpuGovernor) -> Result<(), &'static str> {
+    POWER_MGMT.lock().set_cpu_governor(governor)
+}
+
 pub mod thermal {
     use alloc::string::String;
     use alloc::vec::Vec;
@@ -177,9 +177,10 @@ impl ProfileManager {
     
     fn apply_display_settings(&self, config: &PowerProfileConfig) -> Result<(), &'static str> {
         // Set display brightness
-        // This would interface with display driver
-        serial_println!("Profile: Display brightness set to {}%", 
-                       config.display_brightness_percent);
+        match crate::gpu::backlight::set_percent(config.display_brightness_percent) {
+            Ok(percent) => serial_println!("Profile: Display brightness set to {}%", percent),
+            Err(e) => serial_println!("Profile: Display brightness not set: {}", e),
+        }
         
         // Set display timeout
         serial_println!("Profile: Display timeout set to {} seconds",
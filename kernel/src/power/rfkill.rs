@@ -0,0 +1,62 @@
+// Radio kill switches
+//
+// The soft block on each kind of radio, which the radio hotkeys and
+// `hotkey` flip. Drivers that bring a radio up are to leave it off while
+// it is blocked; the Wi-Fi and Bluetooth drivers do not look yet.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::serial_println;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radio {
+    Wlan,
+    Bluetooth,
+}
+
+impl Radio {
+    pub fn name(self) -> &'static str {
+        match self {
+            Radio::Wlan => "wlan",
+            Radio::Bluetooth => "bluetooth",
+        }
+    }
+
+    fn state(self) -> &'static AtomicBool {
+        match self {
+            Radio::Wlan => &WLAN_BLOCKED,
+            Radio::Bluetooth => &BLUETOOTH_BLOCKED,
+        }
+    }
+}
+
+pub const RADIOS: [Radio; 2] = [Radio::Wlan, Radio::Bluetooth];
+
+static WLAN_BLOCKED: AtomicBool = AtomicBool::new(false);
+static BLUETOOTH_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+pub fn blocked(radio: Radio) -> bool {
+    radio.state().load(Ordering::Relaxed)
+}
+
+pub fn set_blocked(radio: Radio, blocked: bool) {
+    if radio.state().swap(blocked, Ordering::Relaxed) != blocked {
+        serial_println!("rfkill: {} {}", radio.name(), if blocked { "blocked" } else { "unblocked" });
+    }
+}
+
+/// Flip one radio's block, returning whether it is now blocked
+pub fn toggle(radio: Radio) -> bool {
+    let blocked = !blocked(radio);
+    set_blocked(radio, blocked);
+    blocked
+}
+
+/// Block every radio, or unblock them all if all are blocked already
+pub fn toggle_all() -> bool {
+    let blocked = !RADIOS.iter().all(|&radio| blocked(radio));
+    for radio in RADIOS {
+        set_blocked(radio, blocked);
+    }
+    blocked
+}
@@ -0,0 +1,187 @@
+//! Orderly shutdown and reboot
+//!
+//! `shutdown` takes the system down in order: every process is ended, the
+//! page cache is written back and the disks' own caches flushed, the other
+//! processors are parked, every device is powered down leaves first, and
+//! last the machine is turned off through ACPI S5 or reset. A driver that
+//! hangs on the way would stop it there, so a watchdog armed as the
+//! sequence starts finishes the job from the timer interrupt once
+//! `shutdown.timeout` seconds have passed.
+//!
+//! A system call asks for the sequence with `request`. It then runs from
+//! the main loop rather than on the calling process's stack, which is
+//! among the first things to go.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use crate::boot::cmdline::Param;
+use crate::drivers::disk::DISK_MANAGER;
+use crate::process::executor::EXECUTOR;
+use crate::serial_println;
+use crate::smp::hotplug::{self, HotplugError};
+use crate::smp::SMP_MANAGER;
+use crate::time::clocksource::now_ns;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+// What the processes ended on the way down exit with
+const EXIT_SHUTDOWN: i32 = 1;
+
+static TIMEOUT: Param<i64> =
+    Param::new("shutdown.timeout", 30, "Seconds a shutdown or reboot may take before it is forced; 0 never");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ShutdownAction {
+    PowerOff = 1,
+    Reboot = 2,
+}
+
+impl ShutdownAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            ShutdownAction::PowerOff => "power off",
+            ShutdownAction::Reboot => "reboot",
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ShutdownAction::PowerOff),
+            2 => Some(ShutdownAction::Reboot),
+            _ => None,
+        }
+    }
+}
+
+static STARTED: AtomicBool = AtomicBool::new(false);
+// Codes of the action asked for and the one under way, 0 for none
+static REQUESTED: AtomicU8 = AtomicU8::new(0);
+static ACTION: AtomicU8 = AtomicU8::new(0);
+// When the watchdog forces the action; 0 while it is not armed
+static DEADLINE_NS: AtomicU64 = AtomicU64::new(0);
+
+fn halt() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+// The last step, which takes no locks. Returns only if the machine could
+// not be powered off.
+fn finish(action: ShutdownAction) -> Result<(), &'static str> {
+    match action {
+        ShutdownAction::PowerOff => crate::acpi::power::power_off(),
+        ShutdownAction::Reboot => crate::acpi::power::reboot(),
+    }
+}
+
+// Everything sys_kill lets go of, for every process
+fn end_processes() {
+    let pids: Vec<u32> = EXECUTOR.lock().processes().map(|pcb| pcb.pid).collect();
+    for &pid in &pids {
+        crate::virt::ioctl::release_process(pid);
+        crate::serial::tty::release_process(pid);
+        crate::input::evdev::release_process(pid);
+        crate::nt::wdm::release_process(pid);
+        crate::net::stream::release_process(pid);
+        crate::io_uring::release_process(pid);
+        crate::container::release_process(pid);
+        crate::fs::file_ops::release_process(pid);
+        crate::memory::mmap::release_process(pid);
+        crate::process::fork::release_process(pid);
+        crate::drivers::block::release_process(pid);
+        EXECUTOR.lock().terminate_process(pid, EXIT_SHUTDOWN);
+    }
+    serial_println!("shutdown: {} processes ended", pids.len());
+}
+
+fn flush_disks() {
+    let mut disks = DISK_MANAGER.lock();
+    for index in 0..disks.disk_count() {
+        if let Some(Err(error)) = disks.get_disk(index).map(|disk| disk.flush_cache()) {
+            serial_println!("shutdown: disk {} cache not flushed: {:?}", index, error);
+        }
+    }
+}
+
+// Park every processor but this one; the boot processor cannot be
+fn stop_cpus() {
+    let current = crate::smp::current_cpu_id();
+    let online = SMP_MANAGER.lock().get_online_cpus();
+    for cpu in online.into_iter().filter(|&cpu| cpu != current) {
+        match hotplug::cpu_down(cpu) {
+            Ok(_) | Err(HotplugError::BootCpu) => {}
+            Err(error) => serial_println!("shutdown: CPU {} not stopped: {:?}", cpu, error),
+        }
+    }
+}
+
+/// Take the system down and turn it off or reset it. A second caller
+/// waits for the first. If ACPI cannot power the machine off, it is left
+/// halted.
+pub fn shutdown(action: ShutdownAction) -> ! {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+    serial_println!("shutdown: {} under way", action.name());
+    ACTION.store(action as u8, Ordering::SeqCst);
+    let timeout = TIMEOUT.get();
+    if timeout > 0 {
+        DEADLINE_NS.store(now_ns() + timeout as u64 * NS_PER_SEC, Ordering::SeqCst);
+    }
+    if action == ShutdownAction::PowerOff {
+        // Looked up now, while the ACPI tables can still be locked
+        crate::acpi::power::sleep_type_s5();
+    }
+
+    end_processes();
+    crate::memory::page_cache::sync();
+    flush_disks();
+    stop_cpus();
+    super::device::shutdown_all_devices();
+
+    DEADLINE_NS.store(0, Ordering::SeqCst);
+    if let Err(reason) = finish(action) {
+        serial_println!("shutdown: cannot power off: {}", reason);
+        crate::println!("It is now safe to turn off your computer.");
+    }
+    halt()
+}
+
+/// Ask for `shutdown` to run from the main loop; false if one already has
+/// been
+pub fn request(action: ShutdownAction) -> bool {
+    REQUESTED.compare_exchange(0, action as u8, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+}
+
+/// Run a shutdown that has been asked for
+pub fn poll() {
+    if let Some(action) = ShutdownAction::from_code(REQUESTED.load(Ordering::SeqCst)) {
+        shutdown(action);
+    }
+}
+
+/// From the timer interrupt: finish a shutdown that has taken longer than
+/// `shutdown.timeout`, whatever it is waiting on
+pub fn watchdog_tick() {
+    let deadline = DEADLINE_NS.load(Ordering::Relaxed);
+    if deadline == 0 || now_ns() < deadline {
+        return;
+    }
+    DEADLINE_NS.store(0, Ordering::SeqCst);
+    let Some(action) = ShutdownAction::from_code(ACTION.load(Ordering::SeqCst)) else {
+        return;
+    };
+
+    // Whatever was interrupted may hold the console's locks
+    crate::klog::panic_mode();
+    serial_println!("shutdown: timed out after {} s; forcing {}", TIMEOUT.get(), action.name());
+    if let Err(reason) = finish(action) {
+        serial_println!("shutdown: cannot power off: {}", reason);
+    }
+    halt()
+}
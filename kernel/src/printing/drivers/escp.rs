@@ -1,5 +1,6 @@
 use alloc::{string::String, vec::Vec};
 use super::{PrinterDriver, PrinterCommand};
+use crate::printing::raster::Bitmap;
 
 pub struct ESCPDriver {
     mode: ESCPMode,
@@ -96,6 +97,52 @@ impl ESCPDriver {
         }
     }
 
+    /// Job header for raster pages: ESC/P2 graphics mode in 1/360" units,
+    /// a page `height` dots long with no margins
+    pub fn raster_begin(&self, height: usize) -> Vec<u8> {
+        let length = (height.min(0xFFFF) as u16).to_le_bytes();
+        let mut escp = Vec::new();
+        escp.extend_from_slice(b"\x1B@\x1B(G\x01\x00\x01\x1B(U\x01\x00\x0A");
+        escp.extend_from_slice(b"\x1B(C\x02\x00");
+        escp.extend_from_slice(&length);
+        escp.extend_from_slice(b"\x1B(c\x04\x00\x00\x00");
+        escp.extend_from_slice(&length);
+        escp
+    }
+
+    /// One page printed a dot row at a time at 360 dpi, each row run-length
+    /// encoded (ESC . 1), with the paper moved past blank rows
+    pub fn raster_page(&self, page: &Bitmap) -> Vec<u8> {
+        let width = (page.width.min(0xFFFF) as u16).to_le_bytes();
+        let mut escp = Vec::new();
+        // Rows to move down before the next one is printed
+        let mut advance = 0usize;
+        for y in 0..page.height {
+            let row = page.row(y);
+            if row.iter().all(|&byte| byte == 0) {
+                advance += 1;
+                continue;
+            }
+            while advance > 0 {
+                let step = (advance.min(0x7FFF) as u16).to_le_bytes();
+                escp.extend_from_slice(b"\x1B(v\x02\x00");
+                escp.extend_from_slice(&step);
+                advance -= advance.min(0x7FFF);
+            }
+            escp.extend_from_slice(b"\r\x1B.\x01\x0A\x0A\x01");
+            escp.extend_from_slice(&width);
+            super::packbits(row, &mut escp);
+            advance = 1;
+        }
+        escp.push(0x0C);
+        escp
+    }
+
+    /// Job trailer after the last raster page
+    pub fn raster_end(&self) -> Vec<u8> {
+        b"\x1B@".to_vec()
+    }
+
     fn set_color_mode(&self, mode: crate::printing::ColorMode) -> Vec<u8> {
         let mut cmds = Vec::new();
         
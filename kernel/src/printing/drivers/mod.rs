@@ -134,6 +134,35 @@ impl DriverManager {
     }
 }
 
+/// PackBits run-length encoding, as PCL compression mode 2 and ESC/P2's
+/// ESC . 1 both use it: a count byte n then n+1 literal bytes, or 1-n
+/// (as a negative byte) then one byte repeated that many times
+pub fn packbits(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && run < 128 && data[i + run] == data[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push((1 - run as i32) as u8);
+            out.push(data[i]);
+            i += run;
+            continue;
+        }
+        // Literals up to where the next run starts
+        let start = i;
+        while i < data.len() && i - start < 128 && !(i + 1 < data.len() && data[i + 1] == data[i]) {
+            i += 1;
+        }
+        if i == start {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PrinterInfo {
     pub manufacturer: String,
@@ -1,5 +1,6 @@
 use alloc::{string::String, vec::Vec, format};
 use super::{PrinterDriver, PrinterCommand};
+use crate::printing::raster::Bitmap;
 
 pub struct PCL5Driver {
     current_font: u8,
@@ -77,6 +78,58 @@ impl PCL5Driver {
             _ => 2,
         }
     }
+
+    /// Job header for raster pages: reset, paper, copies and duplex
+    pub fn raster_begin(&self, options: &crate::printing::PrintOptions) -> Vec<u8> {
+        let mut pcl = Vec::new();
+        pcl.extend_from_slice(b"\x1B%-12345X\x1BE");
+        pcl.extend_from_slice(format!("\x1B&l{}A", self.get_paper_size_code(options.paper_size)).as_bytes());
+        pcl.extend_from_slice(format!("\x1B&l{}X", options.copies.max(1)).as_bytes());
+        if options.duplex {
+            pcl.extend_from_slice(b"\x1B&l1S");
+        }
+        // Top margin 0 so raster row 0 is the top of the logical page
+        pcl.extend_from_slice(b"\x1B&l0E");
+        pcl
+    }
+
+    /// One page as raster graphics, each row packed with TIFF PackBits
+    /// (compression mode 2) and runs of blank rows skipped with a Y offset
+    pub fn raster_page(&mut self, page: &Bitmap, dpi: u32) -> Vec<u8> {
+        let mut pcl = Vec::new();
+        pcl.extend_from_slice(format!("\x1B*t{}R", dpi).as_bytes());
+        pcl.extend_from_slice(b"\x1B*p0x0Y\x1B*r0F");
+        pcl.extend_from_slice(format!("\x1B*r{}S", page.width).as_bytes());
+        pcl.extend_from_slice(b"\x1B*r1A\x1B*b2M");
+
+        let mut blank = 0;
+        let mut packed = Vec::new();
+        for y in 0..page.height {
+            let row = page.row(y);
+            let used = row.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+            if used == 0 {
+                blank += 1;
+                continue;
+            }
+            if blank > 0 {
+                pcl.extend_from_slice(format!("\x1B*b{}Y", blank).as_bytes());
+                blank = 0;
+            }
+            packed.clear();
+            super::packbits(&row[..used], &mut packed);
+            pcl.extend_from_slice(format!("\x1B*b{}W", packed.len()).as_bytes());
+            pcl.extend_from_slice(&packed);
+        }
+
+        pcl.extend_from_slice(b"\x1B*rC\x0C");
+        self.page_count += 1;
+        pcl
+    }
+
+    /// Job trailer after the last raster page
+    pub fn raster_end(&self) -> Vec<u8> {
+        b"\x1BE\x1B%-12345X".to_vec()
+    }
 }
 
 impl PrinterDriver for PCL5Driver {
@@ -31,13 +31,7 @@ impl FilterChain {
     }
 
     pub fn process(&self, job: &PrintJob) -> Result<Vec<u8>, &'static str> {
-        let mut data = Vec::new();
-        
-        if let Ok(content) = job.file.read_all() {
-            data = content;
-        } else {
-            return Err("Failed to read file");
-        }
+        let mut data = job.data.to_vec();
         
         let input_format = self.detect_format(&data);
         let target_format = self.get_target_format(job);
@@ -1,5 +1,4 @@
-use alloc::{string::String, vec::Vec};
-use crate::fs::File;
+use alloc::{string::String, sync::Arc, vec::Vec};
 use super::PrintOptions;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -25,7 +24,8 @@ pub struct PrintJob {
     pub printer_id: u32,
     pub user: String,
     pub title: String,
-    pub file: File,
+    /// The document as submitted: PDF, PostScript or text
+    pub data: Arc<Vec<u8>>,
     pub options: PrintOptions,
     pub status: JobStatus,
     pub priority: JobPriority,
@@ -40,43 +40,33 @@ pub struct PrintJob {
 }
 
 impl PrintJob {
-    pub fn new(id: u32, printer_id: u32, file: File, options: PrintOptions) -> Self {
-        let total_pages = Self::calculate_pages(&file, &options);
-        let size_bytes = file.size();
+    pub fn new(
+        id: u32,
+        printer_id: u32,
+        user: String,
+        title: String,
+        data: Vec<u8>,
+        options: PrintOptions,
+    ) -> Self {
+        let size_bytes = data.len() as u64;
         
         Self {
             id,
             printer_id,
-            user: String::from("user"),
-            title: file.name.clone(),
-            file,
-            options: options.clone(),
+            user,
+            title,
+            data: Arc::new(data),
+            secure_pin: options.secure_pin.clone(),
+            options,
             status: JobStatus::Queued,
             priority: JobPriority::Normal,
-            total_pages,
+            total_pages: 0,
             pages_printed: 0,
             size_bytes,
             queued_time: 0,
             start_time: None,
             end_time: None,
             error_message: None,
-            secure_pin: options.secure_pin,
-        }
-    }
-
-    fn calculate_pages(file: &File, options: &PrintOptions) -> u32 {
-        let base_pages = file.size() / 4096 + 1;
-        
-        let pages = match &options.page_range {
-            Some(super::PageRange::Range(start, end)) => end - start + 1,
-            Some(super::PageRange::Pages(pages)) => pages.len() as u32,
-            _ => base_pages as u32,
-        };
-        
-        if options.n_up > 1 {
-            (pages + options.n_up - 1) / options.n_up
-        } else {
-            pages
         }
     }
 
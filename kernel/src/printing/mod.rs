@@ -7,10 +7,18 @@ pub mod filter;
 pub mod pdf;
 pub mod manager;
 pub mod queue;
+pub mod raster;
 
-use alloc::{collections::VecDeque, string::String, vec::Vec, boxed::Box};
+use alloc::{string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::RwLock;
-use crate::fs::File;
+use crate::time::clocksource::now_ns;
+use drivers::PrinterDriver;
+
+// Running jobs are moved on at this interval
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PrinterStatus {
@@ -39,6 +47,43 @@ pub enum PrinterType {
     Network,
 }
 
+/// What a printer is sent: pages rasterized into its own language, or the
+/// document as submitted for printers that read PDF or PostScript
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Pcl,
+    EscP,
+    Raw,
+}
+
+impl Language {
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Pcl => "pcl",
+            Language::EscP => "escp",
+            Language::Raw => "raw",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Language> {
+        match name.to_ascii_lowercase().as_str() {
+            "pcl" => Some(Language::Pcl),
+            "escp" | "esc/p" => Some(Language::EscP),
+            "raw" => Some(Language::Raw),
+            _ => None,
+        }
+    }
+
+    /// Resolution pages are rasterized at
+    pub fn dpi(self) -> u32 {
+        match self {
+            Language::Pcl => 300,
+            Language::EscP => 360,
+            Language::Raw => 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ColorMode {
     Monochrome,
@@ -118,16 +163,17 @@ pub enum PageRange {
     Even,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Printer {
     pub id: u32,
     pub name: String,
     pub description: String,
     pub location: String,
+    /// Where output goes: ipp://, socket:// or file:
+    pub uri: String,
+    pub language: Language,
     pub status: PrinterStatus,
     pub capabilities: PrinterCapabilities,
-    pub job_queue: VecDeque<job::PrintJob>,
-    pub current_job: Option<job::PrintJob>,
     pub total_pages_printed: u64,
     pub supply_levels: SupplyLevels,
     pub is_default: bool,
@@ -180,7 +226,6 @@ impl PrintSubsystem {
         self.drivers.load_builtin_drivers()?;
         self.protocols.init_protocols()?;
         self.discover_printers()?;
-        self.spooler.start()?;
         Ok(())
     }
 
@@ -197,6 +242,8 @@ impl PrintSubsystem {
             name: String::from("PDF Printer"),
             description: String::from("Virtual PDF printer"),
             location: String::from("Local"),
+            uri: String::from("file:/spool/print"),
+            language: Language::Raw,
             status: PrinterStatus::Idle,
             capabilities: PrinterCapabilities {
                 name: String::from("PDF Printer"),
@@ -223,8 +270,6 @@ impl PrintSubsystem {
                 supports_pcl: false,
                 supports_pdf: true,
             },
-            job_queue: VecDeque::new(),
-            current_job: None,
             total_pages_printed: 0,
             supply_levels: SupplyLevels {
                 toner_black: None,
@@ -244,6 +289,44 @@ impl PrintSubsystem {
         Ok(())
     }
 
+    /// Add a printer reached over the network at `uri`
+    pub fn add_network_printer(&mut self, name: &str, uri: &str, language: Language) -> Result<u32, &'static str> {
+        protocols::Destination::parse(uri)?;
+        if self.printers.read().iter().any(|p| p.name.eq_ignore_ascii_case(name)) {
+            return Err("A printer with that name already exists");
+        }
+        let (printer_type, capabilities) = match language {
+            Language::Pcl => (PrinterType::Laser, drivers::pcl::PCL5Driver::new().get_capabilities()),
+            Language::EscP => (PrinterType::Inkjet, drivers::escp::ESCPDriver::new().get_capabilities()),
+            Language::Raw => (PrinterType::Network, drivers::postscript::PostScriptDriver::new().get_capabilities()),
+        };
+        let id = self.generate_printer_id();
+        self.add_printer(Printer {
+            id,
+            name: String::from(name),
+            description: String::from(uri),
+            location: String::from("Network"),
+            uri: String::from(uri),
+            language,
+            status: PrinterStatus::Idle,
+            capabilities: PrinterCapabilities { printer_type, ..capabilities },
+            total_pages_printed: 0,
+            supply_levels: SupplyLevels {
+                toner_black: None,
+                toner_cyan: None,
+                toner_magenta: None,
+                toner_yellow: None,
+                paper_trays: vec![],
+                maintenance_kit: None,
+                waste_toner: None,
+            },
+            is_default: false,
+            is_shared: false,
+            access_control: vec![],
+        });
+        Ok(id)
+    }
+
     fn generate_printer_id(&self) -> u32 {
         let mut counter = self.job_counter.write();
         *counter += 1;
@@ -260,10 +343,22 @@ impl PrintSubsystem {
 
     pub fn remove_printer(&mut self, id: u32) -> Result<(), &'static str> {
         let mut printers = self.printers.write();
+        if !printers.iter().any(|p| p.id == id) {
+            return Err("Printer not found");
+        }
         printers.retain(|p| p.id != id);
+        self.spooler.remove_queue(id);
+        let mut default = self.default_printer.write();
+        if *default == Some(id) {
+            *default = printers.first().map(|p| p.id);
+        }
         Ok(())
     }
 
+    pub fn find_printer(&self, name: &str) -> Option<Printer> {
+        self.printers.read().iter().find(|p| p.name.eq_ignore_ascii_case(name)).cloned()
+    }
+
     pub fn get_printer(&self, id: u32) -> Option<Printer> {
         let printers = self.printers.read();
         printers.iter().find(|p| p.id == id).cloned()
@@ -287,31 +382,43 @@ impl PrintSubsystem {
         *self.default_printer.read()
     }
 
+    /// Queue a document (PDF, PostScript or text) for a printer
     pub fn submit_job(
-        &mut self,
+        &self,
         printer_id: u32,
-        file: File,
+        user: String,
+        title: String,
+        data: Vec<u8>,
         options: PrintOptions,
+        priority: job::JobPriority,
     ) -> Result<u32, &'static str> {
-        let job = job::PrintJob::new(
-            self.generate_job_id(),
-            printer_id,
-            file,
-            options,
-        );
+        if !self.printers.read().iter().any(|p| p.id == printer_id) {
+            return Err("Printer not found");
+        }
+        let mut job = job::PrintJob::new(self.generate_job_id(), printer_id, user, title, data, options);
+        job.set_priority(priority);
         
         let job_id = job.id;
         self.spooler.add_job(job)?;
         Ok(job_id)
     }
 
+    /// Running, queued and recently finished jobs
+    pub fn list_jobs(&self) -> Vec<job::PrintJob> {
+        self.spooler.jobs()
+    }
+
+    pub fn get_job(&self, job_id: u32) -> Option<job::PrintJob> {
+        self.spooler.get_job(job_id)
+    }
+
     fn generate_job_id(&self) -> u32 {
         let mut counter = self.job_counter.write();
         *counter += 1;
         *counter
     }
 
-    pub fn cancel_job(&mut self, job_id: u32) -> Result<(), &'static str> {
+    pub fn cancel_job(&self, job_id: u32) -> Result<(), &'static str> {
         self.spooler.cancel_job(job_id)
     }
 
@@ -337,7 +444,12 @@ impl PrintSubsystem {
 
     pub fn get_printer_status(&self, printer_id: u32) -> Option<PrinterStatus> {
         let printers = self.printers.read();
-        printers.iter().find(|p| p.id == printer_id).map(|p| p.status)
+        let status = printers.iter().find(|p| p.id == printer_id).map(|p| p.status)?;
+        if status == PrinterStatus::Idle && self.spooler.is_busy(printer_id) {
+            Some(PrinterStatus::Printing)
+        } else {
+            Some(status)
+        }
     }
 
     pub fn get_supply_levels(&self, printer_id: u32) -> Option<SupplyLevels> {
@@ -357,4 +469,16 @@ pub fn init() -> Result<(), &'static str> {
 
 pub fn get_subsystem() -> &'static RwLock<Option<PrintSubsystem>> {
     &PRINT_SUBSYSTEM
+}
+
+/// Move queued and running print jobs on; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    if let Some(subsystem) = PRINT_SUBSYSTEM.read().as_ref() {
+        subsystem.spooler.poll(&subsystem.printers.read());
+    }
 }
\ No newline at end of file
@@ -23,6 +23,7 @@ const IPP_TAG_URI: u8 = 0x45;
 const IPP_TAG_KEYWORD: u8 = 0x44;
 const IPP_TAG_NAME: u8 = 0x42;
 const IPP_TAG_INTEGER: u8 = 0x21;
+const IPP_TAG_MIMETYPE: u8 = 0x49;
 
 pub struct IPPClient {
     request_id: u32,
@@ -77,7 +78,15 @@ impl IPPClient {
         attr
     }
 
-    pub fn build_print_job_request(&mut self, printer_uri: &str, job_name: &str, data: &[u8]) -> Vec<u8> {
+    pub fn build_print_job_request(
+        &mut self,
+        printer_uri: &str,
+        job_name: &str,
+        user: &str,
+        format: &str,
+        copies: u32,
+        data: &[u8],
+    ) -> Vec<u8> {
         let mut request = self.build_ipp_header(IPP_OP_PRINT_JOB);
         
         request.push(IPP_TAG_OPERATION);
@@ -100,6 +109,12 @@ impl IPPClient {
             printer_uri.as_bytes()
         ));
         
+        request.extend_from_slice(&self.add_attribute(
+            IPP_TAG_NAME,
+            "requesting-user-name",
+            user.as_bytes()
+        ));
+        
         request.extend_from_slice(&self.add_attribute(
             IPP_TAG_NAME,
             "job-name",
@@ -107,11 +122,20 @@ impl IPPClient {
         ));
         
         request.extend_from_slice(&self.add_attribute(
-            IPP_TAG_NAME,
-            "requesting-user-name",
-            b"user"
+            IPP_TAG_MIMETYPE,
+            "document-format",
+            format.as_bytes()
         ));
         
+        if copies > 1 {
+            request.push(IPP_TAG_JOB);
+            request.extend_from_slice(&self.add_attribute(
+                IPP_TAG_INTEGER,
+                "copies",
+                &copies.to_be_bytes()
+            ));
+        }
+        
         request.push(IPP_TAG_END);
         
         request.extend_from_slice(data);
@@ -178,7 +202,14 @@ pub fn discover_ipp_printers() -> Result<Vec<NetworkPrinterInfo>, &'static str>
 pub fn send_print_job(printer: &NetworkPrinterInfo, data: &[u8]) -> Result<(), &'static str> {
     unsafe {
         if let Some(client) = &mut IPP_CLIENT {
-            let request = client.build_print_job_request(&printer.uri, "Print Job", data);
+            let request = client.build_print_job_request(
+                &printer.uri,
+                "Print Job",
+                "user",
+                "application/octet-stream",
+                1,
+                data,
+            );
             send_ipp_request(&printer.host, printer.port, &request)?;
             Ok(())
         } else {
@@ -205,4 +236,128 @@ fn send_ipp_request(host: &str, port: u16, request: &[u8]) -> Result<Vec<u8>, &'
 
 fn parse_printer_status(response: &[u8]) -> Result<crate::printing::PrinterStatus, &'static str> {
     Ok(crate::printing::PrinterStatus::Idle)
-}
\ No newline at end of file
+}
+// How long a printer has to answer once the whole request is out
+const RESPONSE_TIMEOUT_NS: u64 = 60_000_000_000;
+
+/// A Print-Job request posted over HTTP, driven forward by `poll`
+pub struct PrintJobRequest {
+    stream: super::jetdirect::Stream,
+    response: Vec<u8>,
+    sent_at: Option<u64>,
+}
+
+impl PrintJobRequest {
+    pub fn start(
+        host: &str,
+        port: u16,
+        path: &str,
+        job: &crate::printing::job::PrintJob,
+        format: &str,
+        copies: u32,
+        data: &[u8],
+    ) -> Result<PrintJobRequest, &'static str> {
+        let printer_uri = format!("ipp://{}:{}{}", host, port, path);
+        let body =
+            IPPClient::new().build_print_job_request(&printer_uri, &job.title, &job.user, format, copies, data);
+        let header = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/ipp\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            path,
+            host,
+            port,
+            body.len()
+        );
+        let mut request = header.into_bytes();
+        request.extend_from_slice(&body);
+        Ok(PrintJobRequest {
+            stream: super::jetdirect::Stream::connect(host, port, request)?,
+            response: Vec::new(),
+            sent_at: None,
+        })
+    }
+
+    /// The printer's id for the job once it has accepted it
+    pub fn poll(&mut self) -> Result<Option<u32>, &'static str> {
+        if !self.stream.poll()? {
+            return Ok(None);
+        }
+        let now = crate::time::clocksource::now_ns();
+        let sent_at = *self.sent_at.get_or_insert(now);
+        let closed = self.stream.peer_closed();
+        loop {
+            let chunk = self.stream.receive();
+            if chunk.is_empty() {
+                break;
+            }
+            self.response.extend_from_slice(&chunk);
+        }
+        if let Some(result) = parse_print_job_response(&self.response) {
+            return result.map(Some);
+        }
+        if closed {
+            return Err("printer closed the connection without answering");
+        }
+        if now.saturating_sub(sent_at) > RESPONSE_TIMEOUT_NS {
+            return Err("printer did not answer");
+        }
+        Ok(None)
+    }
+
+    /// (bytes sent, bytes in all)
+    pub fn progress(&self) -> (usize, usize) {
+        self.stream.progress()
+    }
+}
+
+// The job id from an HTTP response carrying an IPP Print-Job response,
+// None while more of it is still to come
+fn parse_print_job_response(response: &[u8]) -> Option<Result<u32, &'static str>> {
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let header = String::from_utf8_lossy(&response[..end]).to_ascii_lowercase();
+    let status = header.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200) => {}
+        Some(401) | Some(403) => return Some(Err("printer refused the job: not authorized")),
+        Some(404) => return Some(Err("no such printer at that address")),
+        _ => return Some(Err("printer answered with an HTTP error")),
+    }
+    let mut body = &response[end + 4..];
+    if header.contains("transfer-encoding: chunked") {
+        // The whole response fits in the first chunk
+        let line = body.windows(2).position(|w| w == b"\r\n")?;
+        body = &body[line + 2..];
+    }
+    if body.len() < 8 {
+        return None;
+    }
+    let status = u16::from_be_bytes([body[2], body[3]]);
+    match status {
+        0x0000..=0x00FF => {}
+        0x040A => return Some(Err("printer does not take this document format")),
+        0x0400..=0x04FF => return Some(Err("printer rejected the request")),
+        _ => return Some(Err("printer could not take the job")),
+    }
+
+    // Walk the attribute groups for job-id
+    let mut pos = 8;
+    loop {
+        let tag = *body.get(pos)?;
+        pos += 1;
+        if tag == IPP_TAG_END {
+            return Some(Ok(0));
+        }
+        if tag < 0x10 {
+            continue;
+        }
+        let name_length = u16::from_be_bytes([*body.get(pos)?, *body.get(pos + 1)?]) as usize;
+        let name = body.get(pos + 2..pos + 2 + name_length)?;
+        pos += 2 + name_length;
+        let value_length = u16::from_be_bytes([*body.get(pos)?, *body.get(pos + 1)?]) as usize;
+        let value = body.get(pos + 2..pos + 2 + value_length)?;
+        pos += 2 + value_length;
+        if tag == IPP_TAG_INTEGER && name == b"job-id" && value.len() == 4 {
+            return Some(Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]])));
+        }
+    }
+}
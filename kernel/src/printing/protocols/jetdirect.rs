@@ -0,0 +1,77 @@
+//! Raw printing over TCP port 9100 (HP JetDirect, AppSocket)
+//!
+//! The printer takes whatever arrives on the connection as the job and
+//! prints it once the connection closes. `Stream` is also what IPP sends
+//! its requests over.
+
+use alloc::vec::Vec;
+
+use crate::net::tcp::{self, TcpState};
+use crate::time::clocksource::now_ns;
+
+pub const PORT: u16 = 9100;
+
+// How long a printer has to accept the connection
+const CONNECT_TIMEOUT_NS: u64 = 10_000_000_000;
+// Most taken from the receive buffer at once
+const RECEIVE_CHUNK: usize = 4096;
+
+/// Data going out over a TCP connection as fast as the send window allows
+pub struct Stream {
+    conn: u64,
+    data: Vec<u8>,
+    sent: usize,
+    opened: u64,
+}
+
+impl Stream {
+    pub fn connect(host: &str, port: u16, data: Vec<u8>) -> Result<Stream, &'static str> {
+        let address = crate::net::dns::resolve_hostname(host).ok_or("printer host not found")?;
+        let conn = tcp::connect(crate::net::socket::ephemeral_port(), address, port)?;
+        Ok(Stream { conn, data, sent: 0, opened: now_ns() })
+    }
+
+    /// Send what the window allows, true once every byte has gone out
+    pub fn poll(&mut self) -> Result<bool, &'static str> {
+        if self.sent == self.data.len() {
+            return Ok(true);
+        }
+        match tcp::state(self.conn) {
+            Some(TcpState::Established) => {}
+            Some(TcpState::SynSent) | Some(TcpState::SynReceived) => {
+                if now_ns().saturating_sub(self.opened) > CONNECT_TIMEOUT_NS {
+                    return Err("printer did not answer");
+                }
+                return Ok(false);
+            }
+            _ => return Err("printer closed the connection"),
+        }
+        let window = tcp::send_window(self.conn).min(self.data.len() - self.sent);
+        if window > 0 {
+            tcp::send(self.conn, &self.data[self.sent..self.sent + window])?;
+            self.sent += window;
+        }
+        Ok(self.sent == self.data.len())
+    }
+
+    /// (bytes sent, bytes in all)
+    pub fn progress(&self) -> (usize, usize) {
+        (self.sent, self.data.len())
+    }
+
+    /// Whatever the printer has sent back so far
+    pub fn receive(&mut self) -> Vec<u8> {
+        tcp::recv(self.conn, RECEIVE_CHUNK).unwrap_or_default()
+    }
+
+    /// Whether the printer has finished sending
+    pub fn peer_closed(&self) -> bool {
+        !matches!(tcp::state(self.conn), Some(TcpState::Established))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = tcp::close(self.conn);
+    }
+}
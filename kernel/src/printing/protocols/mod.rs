@@ -1,10 +1,11 @@
 pub mod usb;
 pub mod ipp;
+pub mod jetdirect;
 pub mod lpd;
 pub mod smb;
 pub mod mdns;
 
-use alloc::{vec::Vec, string::String, collections::BTreeMap};
+use alloc::{vec::Vec, string::{String, ToString}, collections::BTreeMap, format};
 use spin::RwLock;
 
 pub struct ProtocolManager {
@@ -212,4 +213,116 @@ pub fn send_data(printer_id: u32, data: Vec<u8>) -> Result<(), &'static str> {
     } else {
         Err("Print subsystem not initialized")
     }
-}
\ No newline at end of file
+}
+
+/// Where a printer's output goes, from its URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    /// ipp://host[:port]/path
+    Ipp { host: String, port: u16, path: String },
+    /// socket://host[:port], raw data to JetDirect port 9100
+    Socket { host: String, port: u16 },
+    /// file:/directory, a file a job
+    File(String),
+}
+
+impl Destination {
+    pub fn parse(uri: &str) -> Result<Destination, &'static str> {
+        if let Some(path) = uri.strip_prefix("file:") {
+            let path = path.trim_start_matches("//");
+            if !path.starts_with('/') {
+                return Err("file: URI needs an absolute path");
+            }
+            return Ok(Destination::File(path.to_string()));
+        }
+        let (scheme, rest) = uri.split_once("://").ok_or("printer URI needs a scheme")?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse::<u16>().map_err(|_| "bad port in printer URI")?)),
+            None => (authority, None),
+        };
+        if host.is_empty() {
+            return Err("printer URI has no host");
+        }
+        match scheme {
+            "ipp" | "http" => Ok(Destination::Ipp {
+                host: host.to_string(),
+                port: port.unwrap_or(631),
+                path: if path.is_empty() { String::from("/ipp/print") } else { path.to_string() },
+            }),
+            "socket" => Ok(Destination::Socket { host: host.to_string(), port: port.unwrap_or(jetdirect::PORT) }),
+            "ipps" => Err("ipps needs TLS, use ipp"),
+            _ => Err("printer URI scheme not supported"),
+        }
+    }
+}
+
+/// A job's output on its way to a printer, a little more each poll
+pub enum Transport {
+    Ipp(ipp::PrintJobRequest),
+    Socket(jetdirect::Stream),
+    File(String, Vec<u8>),
+}
+
+impl Transport {
+    /// Start delivering `data`, a document of type `format`; `copies`
+    /// is asked of printers that take it as a job attribute
+    pub fn open(
+        destination: &Destination,
+        job: &super::job::PrintJob,
+        format: &str,
+        copies: u32,
+        data: Vec<u8>,
+    ) -> Result<Transport, &'static str> {
+        match destination {
+            Destination::Ipp { host, port, path } => {
+                Ok(Transport::Ipp(ipp::PrintJobRequest::start(host, *port, path, job, format, copies, &data)?))
+            }
+            Destination::Socket { host, port } => Ok(Transport::Socket(jetdirect::Stream::connect(host, *port, data)?)),
+            Destination::File(directory) => {
+                let extension = match format {
+                    "application/pdf" => "pdf",
+                    "application/postscript" => "ps",
+                    "text/plain" => "txt",
+                    "application/vnd.hp-pcl" => "pcl",
+                    _ => "prn",
+                };
+                Ok(Transport::File(format!("{}/job{}.{}", directory.trim_end_matches('/'), job.id, extension), data))
+            }
+        }
+    }
+
+    /// True once the printer has the whole job
+    pub fn poll(&mut self) -> Result<bool, &'static str> {
+        match self {
+            Transport::Ipp(request) => match request.poll()? {
+                Some(printer_job) => {
+                    crate::serial_println!("print: printer took the job as its job {}", printer_job);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            Transport::Socket(stream) => stream.poll(),
+            Transport::File(path, data) => {
+                let mut vfs = crate::fs::vfs::VFS.lock();
+                for (slash, _) in path.match_indices('/').skip(1) {
+                    let _ = vfs.create_directory_unchecked(&path[..slash]);
+                }
+                vfs.write_file_unchecked(path, data).map_err(|_| "could not write the output file")?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// (bytes sent, bytes in all)
+    pub fn progress(&self) -> (usize, usize) {
+        match self {
+            Transport::Ipp(request) => request.progress(),
+            Transport::Socket(stream) => stream.progress(),
+            Transport::File(_, data) => (0, data.len()),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Inflate, the decompressor behind PDF's FlateDecode and PNG images
+//
+// A plain DEFLATE decoder after RFC 1951, decoding Huffman codes a bit at
+// a time by counting codes of each length. zlib's two-byte header is
+// skipped and its checksum not checked.
+
+use alloc::{vec, vec::Vec};
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order code length code lengths come in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+const MAX_BITS: usize = 15;
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, &'static str> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or("deflate: data ends early")?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Drop what is left of the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+struct Huffman {
+    // Codes of each length
+    counts: [u16; MAX_BITS + 1],
+    // Symbols in code order
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = alloc::vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, &'static str> {
+        // The first code of each length, and where its symbols start
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied().ok_or("deflate: bad code");
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("deflate: bad code")
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), &'static str> {
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.take(3)? as u8;
+    }
+    let code_length_table = Huffman::new(&lengths);
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (value, repeat) = match code_length_table.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("deflate: repeat with nothing before")?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            _ => (0, 11 + bits.take(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    if lengths.len() > literals + distances {
+        return Err("deflate: code lengths overrun");
+    }
+    Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, literal: &Huffman, distance: &Huffman) -> Result<(), &'static str> {
+    loop {
+        let symbol = literal.decode(bits)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err("deflate: bad length");
+        }
+        let length = LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distance.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err("deflate: bad distance");
+        }
+        let back = DISTANCE_BASE[symbol] as usize + bits.take(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if back > out.len() {
+            return Err("deflate: distance before the start");
+        }
+        for _ in 0..length {
+            out.push(out[out.len() - back]);
+        }
+    }
+}
+
+/// Decompress a zlib stream, or raw DEFLATE data
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let zlib = data.len() >= 2 && data[0] & 0x0F == 8 && (data[0] as u16 * 256 + data[1] as u16) % 31 == 0;
+    let mut bits = Bits { data: &data[if zlib { 2 } else { 0 }..], pos: 0, buffer: 0, count: 0 };
+    let mut out = Vec::with_capacity(data.len() * 4);
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let length = bits.take(16)?;
+                if bits.take(16)? != !length & 0xFFFF {
+                    return Err("deflate: stored block length mismatch");
+                }
+                let start = bits.pos;
+                let block = bits.data.get(start..start + length as usize).ok_or("deflate: data ends early")?;
+                out.extend_from_slice(block);
+                bits.pos += length as usize;
+            }
+            1 => {
+                let (literal, distance) = fixed_tables();
+                codes(&mut bits, &mut out, &literal, &distance)?;
+            }
+            2 => {
+                let (literal, distance) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut out, &literal, &distance)?;
+            }
+            _ => return Err("deflate: bad block type"),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Undo PNG row filters: each `row_length` bytes of pixels led by the
+/// filter type, with `pixel` bytes to a whole pixel
+pub fn unfilter(data: &[u8], pixel: usize, row_length: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut previous = vec![0u8; row_length];
+    for row in data.chunks(row_length + 1) {
+        let (kind, row) = (row[0], &row[1..]);
+        let mut current = row.to_vec();
+        for i in 0..current.len() {
+            let left = if i >= pixel { current[i - pixel] } else { 0 };
+            let up = previous.get(i).copied().unwrap_or(0);
+            let up_left = if i >= pixel { previous.get(i - pixel).copied().unwrap_or(0) } else { 0 };
+            current[i] = current[i].wrapping_add(match kind {
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => {
+                    let estimate = left as i16 + up as i16 - up_left as i16;
+                    let a = (estimate - left as i16).abs();
+                    let b = (estimate - up as i16).abs();
+                    let c = (estimate - up_left as i16).abs();
+                    if a <= b && a <= c {
+                        left
+                    } else if b <= c {
+                        up
+                    } else {
+                        up_left
+                    }
+                }
+                _ => 0,
+            });
+        }
+        out.extend_from_slice(&current);
+        previous = current;
+    }
+    out
+}
@@ -0,0 +1,532 @@
+// Rasterizing documents for printers that take bitmaps
+//
+// A page is drawn at the printer's resolution into a bitmap of a bit a
+// pixel, 1 for black. Paths are flattened into polygons and filled a
+// scanline at a time; a stroke is filled as a quad per segment with a
+// square over each joint. Gray, and colour taken to gray, is dithered
+// with an 8x8 Bayer matrix. Clipping keeps only the clip path's bounding
+// box, which is exact for the rectangles nearly every clip is.
+//
+// No font files are read: text is drawn with the 8x8 console font scaled
+// into each glyph's box, so lines and words land where the document puts
+// them but letters do not look like its fonts.
+
+pub mod inflate;
+pub mod pdf;
+pub mod postscript;
+pub mod text;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::PaperSize;
+
+pub const POINTS_PER_INCH: f32 = 72.0;
+
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// Segments a Bézier curve is flattened into
+const CURVE_SEGMENTS: usize = 16;
+
+pub(crate) fn floor(x: f32) -> f32 {
+    let truncated = x as i64 as f32;
+    if truncated > x {
+        truncated - 1.0
+    } else {
+        truncated
+    }
+}
+
+fn ceil(x: f32) -> f32 {
+    -floor(-x)
+}
+
+pub(crate) fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut guess = if x > 1.0 { x / 2.0 } else { 1.0 };
+    for _ in 0..24 {
+        guess = (guess + x / guess) * 0.5;
+    }
+    guess
+}
+
+/// Gray level, 0 black, of an RGB colour with components from 0 to 1
+pub fn gray_of_rgb(r: f32, g: f32, b: f32) -> u8 {
+    gray_of_level(0.299 * r + 0.587 * g + 0.114 * b)
+}
+
+/// Gray level of a CMYK colour with components from 0 to 1
+pub fn gray_of_cmyk(c: f32, m: f32, y: f32, k: f32) -> u8 {
+    gray_of_level(1.0 - (0.3 * c + 0.59 * m + 0.11 * y + k))
+}
+
+/// Gray level of a DeviceGray value from 0 to 1
+pub fn gray_of_level(level: f32) -> u8 {
+    (level.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+/// Size of a sheet in points
+pub fn paper_points(size: PaperSize) -> (f32, f32) {
+    match size {
+        PaperSize::Letter => (612.0, 792.0),
+        PaperSize::Legal => (612.0, 1008.0),
+        PaperSize::A4 => (595.0, 842.0),
+        PaperSize::A3 => (842.0, 1191.0),
+        PaperSize::A5 => (420.0, 595.0),
+        PaperSize::Envelope => (297.0, 684.0),
+        PaperSize::Custom(w, h) => (w as f32, h as f32),
+    }
+}
+
+/// One page, a bit a pixel with 1 for black; each row starts on a byte,
+/// with the leftmost pixel in its top bit
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub bits: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(width: usize, height: usize) -> Bitmap {
+        let stride = width.div_ceil(8);
+        Bitmap { width, height, stride, bits: vec![0; stride * height] }
+    }
+
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.bits[y * self.stride..(y + 1) * self.stride]
+    }
+
+    /// Whether nothing is drawn on the page
+    pub fn is_blank(&self) -> bool {
+        self.bits.iter().all(|&byte| byte == 0)
+    }
+
+    // Paint pixels x0..x1 of row y with a gray level
+    fn span(&mut self, y: usize, x0: usize, x1: usize, gray: u8) {
+        let x1 = x1.min(self.width);
+        let thresholds = &BAYER[y % 8];
+        let row = &mut self.bits[y * self.stride..(y + 1) * self.stride];
+        for x in x0..x1 {
+            let mask = 0x80 >> (x % 8);
+            if gray < thresholds[x % 8] * 4 + 2 {
+                row[x / 8] |= mask;
+            } else {
+                row[x / 8] &= !mask;
+            }
+        }
+    }
+}
+
+/// An affine transform [a b c d e f], as PDF and PostScript write it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix(pub [f32; 6]);
+
+impl Matrix {
+    pub const IDENTITY: Matrix = Matrix([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+
+    pub fn translate(tx: f32, ty: f32) -> Matrix {
+        Matrix([1.0, 0.0, 0.0, 1.0, tx, ty])
+    }
+
+    pub fn scale(sx: f32, sy: f32) -> Matrix {
+        Matrix([sx, 0.0, 0.0, sy, 0.0, 0.0])
+    }
+
+    /// `self`, then `other`
+    pub fn then(self, other: Matrix) -> Matrix {
+        let [a, b, c, d, e, f] = self.0;
+        let [a2, b2, c2, d2, e2, f2] = other.0;
+        Matrix([
+            a * a2 + b * c2,
+            a * b2 + b * d2,
+            c * a2 + d * c2,
+            c * b2 + d * d2,
+            e * a2 + f * c2 + e2,
+            e * b2 + f * d2 + f2,
+        ])
+    }
+
+    pub fn apply(self, x: f32, y: f32) -> (f32, f32) {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + c * y + e, b * x + d * y + f)
+    }
+
+    pub fn invert(self) -> Option<Matrix> {
+        let [a, b, c, d, e, f] = self.0;
+        let det = a * d - b * c;
+        if det == 0.0 {
+            return None;
+        }
+        Some(Matrix([d / det, -b / det, -c / det, a / det, (c * f - d * e) / det, (b * e - a * f) / det]))
+    }
+
+    // How much a length grows, for line widths
+    fn stretch(self) -> f32 {
+        let [a, b, c, d, _, _] = self.0;
+        let det = a * d - b * c;
+        sqrt(if det < 0.0 { -det } else { det })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FillRule {
+    NonZero,
+    EvenOdd,
+}
+
+/// What `save` keeps and `restore` brings back
+#[derive(Debug, Clone)]
+pub struct GraphicsState {
+    pub ctm: Matrix,
+    pub fill: u8,
+    pub stroke: u8,
+    pub line_width: f32,
+    // Device rectangle x0, y0, x1, y1
+    clip: [f32; 4],
+}
+
+pub struct Canvas {
+    pub bitmap: Bitmap,
+    /// Page size in points
+    pub size: (f32, f32),
+    pub state: GraphicsState,
+    // User space to device space on a fresh page
+    base: Matrix,
+    stack: Vec<GraphicsState>,
+    // Subpaths in device space
+    path: Vec<Vec<(f32, f32)>>,
+}
+
+impl Canvas {
+    /// A blank page `width` by `height` points at `dpi`
+    pub fn new(width: f32, height: f32, dpi: (u32, u32)) -> Canvas {
+        let (sx, sy) = (dpi.0 as f32 / POINTS_PER_INCH, dpi.1 as f32 / POINTS_PER_INCH);
+        let bitmap = Bitmap::new((width * sx) as usize, (height * sy) as usize);
+        // Y runs up the page in user space and down the bitmap
+        let base = Matrix([sx, 0.0, 0.0, -sy, 0.0, bitmap.height as f32]);
+        let state = GraphicsState {
+            ctm: base,
+            fill: 0,
+            stroke: 0,
+            line_width: 1.0,
+            clip: [0.0, 0.0, bitmap.width as f32, bitmap.height as f32],
+        };
+        Canvas { bitmap, size: (width, height), state, base, stack: Vec::new(), path: Vec::new() }
+    }
+
+    /// Blank the page and put the graphics state back for the next one
+    pub fn clear(&mut self) {
+        self.bitmap.bits.fill(0);
+        self.clear_state();
+    }
+
+    /// Put the graphics state back as a fresh page has it
+    pub fn clear_state(&mut self) {
+        self.stack.clear();
+        self.path.clear();
+        self.state = GraphicsState {
+            ctm: self.base,
+            fill: 0,
+            stroke: 0,
+            line_width: 1.0,
+            clip: [0.0, 0.0, self.bitmap.width as f32, self.bitmap.height as f32],
+        };
+    }
+
+    pub fn save(&mut self) {
+        self.stack.push(self.state.clone());
+    }
+
+    pub fn restore(&mut self) {
+        if let Some(state) = self.stack.pop() {
+            self.state = state;
+        }
+    }
+
+    /// Apply `matrix` ahead of the current transform
+    pub fn concat(&mut self, matrix: Matrix) {
+        self.state.ctm = matrix.then(self.state.ctm);
+    }
+
+    pub fn move_to(&mut self, x: f32, y: f32) {
+        let point = self.state.ctm.apply(x, y);
+        self.path.push(vec![point]);
+    }
+
+    pub fn line_to(&mut self, x: f32, y: f32) {
+        let point = self.state.ctm.apply(x, y);
+        match self.path.last_mut() {
+            Some(subpath) => subpath.push(point),
+            None => self.path.push(vec![point]),
+        }
+    }
+
+    pub fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x3: f32, y3: f32) {
+        let Some(&(x0, y0)) = self.path.last().and_then(|subpath| subpath.last()) else {
+            return self.move_to(x3, y3);
+        };
+        let ctm = self.state.ctm;
+        let (x1, y1) = ctm.apply(x1, y1);
+        let (x2, y2) = ctm.apply(x2, y2);
+        let (x3, y3) = ctm.apply(x3, y3);
+        let subpath = self.path.last_mut().unwrap();
+        for i in 1..=CURVE_SEGMENTS {
+            let t = i as f32 / CURVE_SEGMENTS as f32;
+            let u = 1.0 - t;
+            let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            subpath.push((a * x0 + b * x1 + c * x2 + d * x3, a * y0 + b * y1 + c * y2 + d * y3));
+        }
+    }
+
+    pub fn close_path(&mut self) {
+        if let Some(&first) = self.path.last().and_then(|subpath| subpath.first()) {
+            self.path.last_mut().unwrap().push(first);
+            // Drawing goes on from the start of the closed subpath
+            self.path.push(vec![first]);
+        }
+    }
+
+    pub fn rect(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.move_to(x, y);
+        self.line_to(x + width, y);
+        self.line_to(x + width, y + height);
+        self.line_to(x, y + height);
+        self.close_path();
+    }
+
+    /// The current point in user space
+    pub fn current_point(&self) -> Option<(f32, f32)> {
+        let &(x, y) = self.path.last()?.last()?;
+        Some(self.state.ctm.invert()?.apply(x, y))
+    }
+
+    pub fn new_path(&mut self) {
+        self.path.clear();
+    }
+
+    pub fn fill(&mut self, rule: FillRule) {
+        let path = core::mem::take(&mut self.path);
+        self.fill_polygons(&path, rule, self.state.fill);
+    }
+
+    /// Fill the current path, then stroke it
+    pub fn fill_and_stroke(&mut self, rule: FillRule) {
+        let path = self.path.clone();
+        self.fill(rule);
+        self.path = path;
+        self.stroke();
+    }
+
+    pub fn stroke(&mut self) {
+        let path = core::mem::take(&mut self.path);
+        let half = (self.state.line_width * self.state.ctm.stretch()).max(1.0) / 2.0;
+        let mut polygons = Vec::new();
+        for subpath in &path {
+            for pair in subpath.windows(2) {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                let (dx, dy) = (x1 - x0, y1 - y0);
+                let length = sqrt(dx * dx + dy * dy);
+                if length == 0.0 {
+                    continue;
+                }
+                let (nx, ny) = (-dy / length * half, dx / length * half);
+                polygons.push(vec![(x0 + nx, y0 + ny), (x1 + nx, y1 + ny), (x1 - nx, y1 - ny), (x0 - nx, y0 - ny)]);
+            }
+            for &(x, y) in subpath.iter().skip(1).take(subpath.len().saturating_sub(2)) {
+                polygons.push(vec![
+                    (x - half, y - half),
+                    (x + half, y - half),
+                    (x + half, y + half),
+                    (x - half, y + half),
+                ]);
+            }
+        }
+        self.fill_polygons(&polygons, FillRule::NonZero, self.state.stroke);
+    }
+
+    /// Narrow the clip to the current path's bounding box; the path is kept
+    /// for the painting operator that follows
+    pub fn clip(&mut self) {
+        let mut bounds = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+        for &(x, y) in self.path.iter().flatten() {
+            bounds = [bounds[0].min(x), bounds[1].min(y), bounds[2].max(x), bounds[3].max(y)];
+        }
+        if bounds[0] > bounds[2] {
+            return;
+        }
+        let clip = &mut self.state.clip;
+        *clip = [clip[0].max(bounds[0]), clip[1].max(bounds[1]), clip[2].min(bounds[2]), clip[3].min(bounds[3])];
+    }
+
+    fn fill_polygons(&mut self, polygons: &[Vec<(f32, f32)>], rule: FillRule, gray: u8) {
+        // Edges as (top x, top y, bottom x, bottom y, direction)
+        let mut edges = Vec::new();
+        let (mut top, mut bottom) = (f32::MAX, f32::MIN);
+        for polygon in polygons.iter().filter(|polygon| polygon.len() > 1) {
+            for (i, &(x0, y0)) in polygon.iter().enumerate() {
+                let (x1, y1) = polygon[(i + 1) % polygon.len()];
+                if y0 == y1 {
+                    continue;
+                }
+                top = top.min(y0.min(y1));
+                bottom = bottom.max(y0.max(y1));
+                edges.push(if y0 < y1 { (x0, y0, x1, y1, 1) } else { (x1, y1, x0, y0, -1) });
+            }
+        }
+        if edges.is_empty() {
+            return;
+        }
+        let [clip_x0, clip_y0, clip_x1, clip_y1] = self.state.clip;
+        let first_row = floor(top.max(clip_y0)).max(0.0) as usize;
+        let end_row = (ceil(bottom.min(clip_y1)).max(0.0) as usize).min(self.bitmap.height);
+        let mut crossings: Vec<(f32, i32)> = Vec::new();
+        for y in first_row..end_row {
+            let center = y as f32 + 0.5;
+            crossings.clear();
+            for &(x0, y0, x1, y1, direction) in &edges {
+                if center >= y0 && center < y1 {
+                    crossings.push((x0 + (center - y0) * (x1 - x0) / (y1 - y0), direction));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(core::cmp::Ordering::Equal));
+            let mut winding = 0;
+            for (i, &(x, direction)) in crossings.iter().enumerate() {
+                winding += match rule {
+                    FillRule::NonZero => direction,
+                    FillRule::EvenOdd => 1,
+                };
+                let inside = match rule {
+                    FillRule::NonZero => winding != 0,
+                    FillRule::EvenOdd => winding % 2 == 1,
+                };
+                let Some(&(next, _)) = crossings.get(i + 1).filter(|_| inside) else {
+                    continue;
+                };
+                // Pixels whose centres fall between the two crossings
+                let from = ceil(x.max(clip_x0) - 0.5).max(0.0) as usize;
+                let to = ceil(next.min(clip_x1) - 0.5).max(0.0) as usize;
+                if from < to {
+                    self.bitmap.span(y, from, to, gray);
+                }
+            }
+        }
+    }
+
+    /// Draw a character from the console font; `glyph_space` takes the
+    /// glyph's box, its advance wide and an em high with the baseline at
+    /// 0, to the page
+    pub fn glyph(&mut self, c: char, glyph_space: Matrix, advance: f32) {
+        let Some(rows) = crate::graphics::font::glyph(c) else {
+            return;
+        };
+        let to_device = glyph_space.then(self.state.ctm);
+        let column = advance / 8.0;
+        let mut polygons = Vec::new();
+        for (row, &bits) in rows.iter().enumerate() {
+            // Row 6 sits on the baseline and row 7 hangs below it
+            let y0 = (6 - row as i32) as f32 / 8.0;
+            let y1 = y0 + 1.0 / 8.0;
+            let mut col = 0;
+            while col < 8 {
+                if bits >> col & 1 == 0 {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < 8 && bits >> col & 1 != 0 {
+                    col += 1;
+                }
+                let (x0, x1) = (start as f32 * column, col as f32 * column);
+                polygons.push(vec![
+                    to_device.apply(x0, y0),
+                    to_device.apply(x1, y0),
+                    to_device.apply(x1, y1),
+                    to_device.apply(x0, y1),
+                ]);
+            }
+        }
+        self.fill_polygons(&polygons, FillRule::NonZero, self.state.fill);
+    }
+
+    /// Draw an image of gray samples, top row first, into the unit square;
+    /// a mask paints the fill colour where its sample is 0
+    pub fn image(&mut self, width: usize, height: usize, samples: &[u8], mask: bool) {
+        if width == 0 || height == 0 || samples.len() < width * height {
+            return;
+        }
+        let ctm = self.state.ctm;
+        let Some(inverse) = ctm.invert() else {
+            return;
+        };
+        let corners = [ctm.apply(0.0, 0.0), ctm.apply(1.0, 0.0), ctm.apply(0.0, 1.0), ctm.apply(1.0, 1.0)];
+        let [clip_x0, clip_y0, clip_x1, clip_y1] = self.state.clip;
+        let x0 = corners.iter().fold(f32::MAX, |m, c| m.min(c.0)).max(clip_x0).max(0.0) as usize;
+        let y0 = corners.iter().fold(f32::MAX, |m, c| m.min(c.1)).max(clip_y0).max(0.0) as usize;
+        let x1 = (ceil(corners.iter().fold(f32::MIN, |m, c| m.max(c.0)).min(clip_x1)).max(0.0) as usize)
+            .min(self.bitmap.width);
+        let y1 = (ceil(corners.iter().fold(f32::MIN, |m, c| m.max(c.1)).min(clip_y1)).max(0.0) as usize)
+            .min(self.bitmap.height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (u, v) = inverse.apply(x as f32 + 0.5, y as f32 + 0.5);
+                if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+                    continue;
+                }
+                let row = (((1.0 - v) * height as f32) as usize).min(height - 1);
+                let sample = samples[row * width + (u * width as f32) as usize];
+                if !mask {
+                    self.bitmap.span(y, x, x + 1, sample);
+                } else if sample == 0 {
+                    self.bitmap.span(y, x, x + 1, self.state.fill);
+                }
+            }
+        }
+    }
+}
+
+/// A document drawn a page at a time
+pub trait Source: Send {
+    /// Draw the next page onto a blank canvas, false once there are no
+    /// more pages
+    fn next_page(&mut self, canvas: &mut Canvas) -> Result<bool, &'static str>;
+
+    /// Pages in the document, if that is known before they are drawn
+    fn page_count(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The MIME type of a document `open` reads
+pub fn format(data: &[u8]) -> &'static str {
+    if data.starts_with(b"%PDF") {
+        "application/pdf"
+    } else if data.starts_with(b"%!") {
+        "application/postscript"
+    } else {
+        "text/plain"
+    }
+}
+
+/// Open a PDF, PostScript or plain text document
+pub fn open(data: Vec<u8>) -> Result<Box<dyn Source>, &'static str> {
+    if data.starts_with(b"%PDF") {
+        Ok(Box::new(pdf::Document::parse(data)?))
+    } else if data.starts_with(b"%!") {
+        Ok(Box::new(postscript::Interpreter::new(data)))
+    } else {
+        Ok(Box::new(text::Text::new(data)))
+    }
+}
@@ -0,0 +1,1041 @@
+// Drawing PDF pages
+//
+// The file is not read through its cross-reference table, which is often
+// wrong after an edit: every `N G obj` in it is parsed instead, a later
+// copy of an object replacing an earlier one, and the objects packed in
+// object streams are added from those. The catalog comes from the
+// trailer, or from a cross-reference stream's dictionary.
+//
+// Content streams are run with paths, colours, text and images. Fonts give
+// only their glyph widths; see mod.rs for how glyphs are drawn. Composite
+// fonts have glyph IDs rather than characters, so their text advances but
+// is not drawn, and text in render mode 3, the hidden text layer of a
+// scanned page, is not drawn either. Images take FlateDecode or no filter;
+// JPEG images and shadings are left out.
+
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inflate::inflate;
+use super::{gray_of_cmyk, gray_of_level, gray_of_rgb, Canvas, FillRule, Matrix, Source};
+
+// How deep page trees and form XObjects may nest
+const MAX_DEPTH: usize = 32;
+// Default width of a glyph, in thousandths of an em
+const DEFAULT_WIDTH: f32 = 600.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Null,
+    Bool(bool),
+    Number(f32),
+    Name(Vec<u8>),
+    String(Vec<u8>),
+    Array(Vec<Object>),
+    Dict(Dict),
+    Ref(u32),
+    /// A stream's dictionary and where its data lies in the file
+    Stream(Dict, usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dict(Vec<(Vec<u8>, Object)>);
+
+impl Dict {
+    pub fn get(&self, key: &[u8]) -> Option<&Object> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+    }
+}
+
+impl Object {
+    fn number(&self) -> Option<f32> {
+        match self {
+            Object::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> Option<&[u8]> {
+        match self {
+            Object::Name(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn dict(&self) -> Option<&Dict> {
+        match self {
+            Object::Dict(dict) | Object::Stream(dict, _, _) => Some(dict),
+            _ => None,
+        }
+    }
+
+    fn array(&self) -> Option<&[Object]> {
+        match self {
+            Object::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b'\x0C' | b'\0')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(byte, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
+}
+
+fn find(data: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    data.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|at| from + at)
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// What the lexer gives: an object, or an operator in a content stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Object(Object),
+    Keyword(Vec<u8>),
+    ArrayEnd,
+    DictEnd,
+}
+
+pub struct Lexer<'a> {
+    pub data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(data: &'a [u8], pos: usize) -> Lexer<'a> {
+        Lexer { data, pos }
+    }
+
+    pub(super) fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    pub(super) fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if is_whitespace(byte) {
+                self.pos += 1;
+            } else if byte == b'%' {
+                while self.peek().is_some_and(|byte| byte != b'\n' && byte != b'\r') {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub(super) fn regular(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.peek().is_some_and(|byte| !is_whitespace(byte) && !is_delimiter(byte)) {
+            self.pos += 1;
+        }
+        &self.data[start..self.pos]
+    }
+
+    pub(super) fn literal_string(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut depth = 1;
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            match byte {
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                b'\\' => {
+                    let Some(escaped) = self.peek() else {
+                        break;
+                    };
+                    self.pos += 1;
+                    let byte = match escaped {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'f' => 0x0C,
+                        b'0'..=b'7' => {
+                            let mut value = (escaped - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(digit @ b'0'..=b'7') => {
+                                        value = value * 8 + (digit - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            value as u8
+                        }
+                        // A backslash before a line end continues the line
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                            continue;
+                        }
+                        b'\n' => continue,
+                        other => other,
+                    };
+                    out.push(byte);
+                    continue;
+                }
+                _ => {}
+            }
+            out.push(byte);
+        }
+        out
+    }
+
+    pub(super) fn hex_string(&mut self) -> Vec<u8> {
+        let mut digits = Vec::new();
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            if byte == b'>' {
+                break;
+            }
+            if let Some(digit) = hex_digit(byte) {
+                digits.push(digit);
+            }
+        }
+        if digits.len() % 2 == 1 {
+            digits.push(0);
+        }
+        digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect()
+    }
+
+    fn name(&mut self) -> Vec<u8> {
+        let raw = self.regular();
+        let mut name = Vec::with_capacity(raw.len());
+        let mut i = 0;
+        while i < raw.len() {
+            match (raw[i], raw.get(i + 1).and_then(|&b| hex_digit(b)), raw.get(i + 2).and_then(|&b| hex_digit(b))) {
+                (b'#', Some(high), Some(low)) => {
+                    name.push(high << 4 | low);
+                    i += 3;
+                }
+                (byte, _, _) => {
+                    name.push(byte);
+                    i += 1;
+                }
+            }
+        }
+        name
+    }
+
+    pub fn token(&mut self) -> Option<Token> {
+        self.skip_whitespace();
+        let byte = self.peek()?;
+        self.pos += 1;
+        let object = match byte {
+            b'/' => Object::Name(self.name()),
+            b'(' => Object::String(self.literal_string()),
+            b'<' if self.peek() == Some(b'<') => {
+                self.pos += 1;
+                return Some(Token::Object(self.dict_body()));
+            }
+            b'<' => Object::String(self.hex_string()),
+            b'>' if self.peek() == Some(b'>') => {
+                self.pos += 1;
+                return Some(Token::DictEnd);
+            }
+            b'[' => return Some(Token::Object(self.array_body())),
+            b']' => return Some(Token::ArrayEnd),
+            b'{' | b'}' | b')' | b'>' => return self.token(),
+            _ => {
+                self.pos -= 1;
+                let word = self.regular();
+                if word.is_empty() {
+                    self.pos += 1;
+                    return self.token();
+                }
+                match word {
+                    b"true" => Object::Bool(true),
+                    b"false" => Object::Bool(false),
+                    b"null" => Object::Null,
+                    _ => match parse_number(word) {
+                        Some(number) => return Some(Token::Object(self.maybe_reference(number))),
+                        None => return Some(Token::Keyword(word.to_vec())),
+                    },
+                }
+            }
+        };
+        Some(Token::Object(object))
+    }
+
+    // A number, or the `N G R` of an indirect reference that starts with it
+    fn maybe_reference(&mut self, number: f32) -> Object {
+        let start = self.pos;
+        if number >= 0.0 && number == (number as u32) as f32 {
+            self.skip_whitespace();
+            let generation = self.regular();
+            if !generation.is_empty() && generation.iter().all(u8::is_ascii_digit) {
+                self.skip_whitespace();
+                if self.regular() == b"R" {
+                    return Object::Ref(number as u32);
+                }
+            }
+        }
+        self.pos = start;
+        Object::Number(number)
+    }
+
+    fn array_body(&mut self) -> Object {
+        let mut items = Vec::new();
+        while let Some(token) = self.token() {
+            match token {
+                Token::Object(object) => items.push(object),
+                Token::ArrayEnd => break,
+                Token::Keyword(_) | Token::DictEnd => {}
+            }
+        }
+        Object::Array(items)
+    }
+
+    fn dict_body(&mut self) -> Object {
+        let mut entries = Vec::new();
+        while let Some(token) = self.token() {
+            match token {
+                Token::Object(Object::Name(key)) => match self.token() {
+                    Some(Token::Object(value)) => entries.push((key, value)),
+                    Some(Token::DictEnd) | None => break,
+                    _ => {}
+                },
+                Token::DictEnd => break,
+                _ => {}
+            }
+        }
+        Object::Dict(Dict(entries))
+    }
+
+    pub fn object(&mut self) -> Option<Object> {
+        match self.token()? {
+            Token::Object(object) => Some(object),
+            _ => None,
+        }
+    }
+}
+
+pub(super) fn parse_number(word: &[u8]) -> Option<f32> {
+    let text = core::str::from_utf8(word).ok()?;
+    if !text.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'+')) {
+        return None;
+    }
+    let (negative, digits) = match text.as_bytes()[0] {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let mut value = 0f32;
+    for digit in whole.bytes().filter(u8::is_ascii_digit) {
+        value = value * 10.0 + (digit - b'0') as f32;
+    }
+    let mut scale = 0.1f32;
+    for digit in fraction.bytes().filter(u8::is_ascii_digit) {
+        value += (digit - b'0') as f32 * scale;
+        scale /= 10.0;
+    }
+    Some(if negative { -value } else { value })
+}
+
+// Undo a PNG predictor, each row led by its filter type byte
+fn unpredict(data: &[u8], parms: Option<&Dict>) -> Vec<u8> {
+    let Some(parms) = parms else {
+        return data.to_vec();
+    };
+    let get = |key: &[u8], default: f32| parms.get(key).and_then(Object::number).unwrap_or(default) as usize;
+    if get(b"Predictor", 1.0) < 10 {
+        return data.to_vec();
+    }
+    let pixel = (get(b"Colors", 1.0) * get(b"BitsPerComponent", 8.0)).div_ceil(8).max(1);
+    let row_length = (get(b"Columns", 1.0) * get(b"Colors", 1.0) * get(b"BitsPerComponent", 8.0)).div_ceil(8);
+    super::inflate::unfilter(data, pixel, row_length)
+}
+
+fn ascii_hex(data: &[u8]) -> Vec<u8> {
+    let mut lexer = Lexer::new(data, 0);
+    lexer.hex_string()
+}
+
+fn ascii85(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut group = [0u32; 5];
+    let mut count = 0;
+    for &byte in data {
+        match byte {
+            b'~' => break,
+            b'z' if count == 0 => out.extend_from_slice(&[0; 4]),
+            b'!'..=b'u' => {
+                group[count] = (byte - b'!') as u32;
+                count += 1;
+                if count == 5 {
+                    let value = group.iter().fold(0u32, |value, &digit| value.wrapping_mul(85).wrapping_add(digit));
+                    out.extend_from_slice(&value.to_be_bytes());
+                    count = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+    if count > 1 {
+        group[count..].fill(84);
+        let value = group.iter().fold(0u32, |value, &digit| value.wrapping_mul(85).wrapping_add(digit));
+        out.extend_from_slice(&value.to_be_bytes()[..count - 1]);
+    }
+    out
+}
+
+pub struct Document {
+    data: Vec<u8>,
+    objects: BTreeMap<u32, Object>,
+    pages: Vec<Page>,
+    next: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Page {
+    dict: Dict,
+    resources: Dict,
+    media_box: [f32; 4],
+}
+
+impl Document {
+    pub fn parse(data: Vec<u8>) -> Result<Document, &'static str> {
+        let mut document = Document { data, objects: BTreeMap::new(), pages: Vec::new(), next: 0 };
+        document.scan_objects();
+        document.unpack_object_streams();
+        let root = document.root().ok_or("pdf: no catalog")?;
+        let pages = document.resolve(root.dict().and_then(|catalog| catalog.get(b"Pages")));
+        document.collect_pages(pages, &Dict::default(), [0.0, 0.0, 612.0, 792.0], 0);
+        if document.pages.is_empty() {
+            return Err("pdf: no pages");
+        }
+        Ok(document)
+    }
+
+    // Every `N G obj` in the file
+    fn scan_objects(&mut self) {
+        let data = &self.data;
+        let mut pos = 0;
+        while let Some(at) = find(data, pos, b"obj") {
+            pos = at + 3;
+            // Back over `N G `
+            let mut start = at;
+            let mut numbers = 0;
+            while numbers < 2 && start > 0 {
+                while start > 0 && is_whitespace(data[start - 1]) {
+                    start -= 1;
+                }
+                let end = start;
+                while start > 0 && data[start - 1].is_ascii_digit() {
+                    start -= 1;
+                }
+                if start == end {
+                    break;
+                }
+                numbers += 1;
+            }
+            if numbers < 2 || start > 0 && !is_whitespace(data[start - 1]) && !is_delimiter(data[start - 1]) {
+                continue;
+            }
+            let Some(number) = core::str::from_utf8(&data[start..at])
+                .ok()
+                .and_then(|text| text.split_ascii_whitespace().next())
+                .and_then(|text| text.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let mut lexer = Lexer::new(data, pos);
+            let Some(object) = lexer.object() else {
+                continue;
+            };
+            let after = lexer.pos;
+            let object = match (object, lexer.token()) {
+                (Object::Dict(dict), Some(Token::Keyword(keyword))) if keyword == b"stream" => {
+                    let mut begin = lexer.pos;
+                    if data.get(begin) == Some(&b'\r') {
+                        begin += 1;
+                    }
+                    if data.get(begin) == Some(&b'\n') {
+                        begin += 1;
+                    }
+                    let length = dict.get(b"Length").and_then(Object::number).map(|length| length as usize);
+                    let end = match length {
+                        Some(length) if data.get(begin + length..).is_some_and(|rest| {
+                            let rest = &rest[..rest.len().min(16)];
+                            find(rest, 0, b"endstream").is_some()
+                        }) =>
+                        {
+                            begin + length
+                        }
+                        _ => {
+                            let mut end = find(data, begin, b"endstream").unwrap_or(data.len());
+                            if end > begin && data[end - 1] == b'\n' {
+                                end -= 1;
+                            }
+                            if end > begin && data[end - 1] == b'\r' {
+                                end -= 1;
+                            }
+                            end
+                        }
+                    };
+                    pos = end;
+                    Object::Stream(dict, begin, end)
+                }
+                (object, _) => {
+                    pos = after;
+                    object
+                }
+            };
+            self.objects.insert(number, object);
+        }
+    }
+
+    // Objects packed in object streams, where not also in the file itself
+    fn unpack_object_streams(&mut self) {
+        let streams: Vec<Object> = self
+            .objects
+            .values()
+            .filter(|object| matches!(object, Object::Stream(dict, _, _)
+                if dict.get(b"Type").and_then(Object::name) == Some(b"ObjStm")))
+            .cloned()
+            .collect();
+        for stream in streams {
+            let Object::Stream(dict, _, _) = &stream else {
+                continue;
+            };
+            let Ok(data) = self.stream_data(&stream) else {
+                continue;
+            };
+            let count = dict.get(b"N").and_then(Object::number).unwrap_or(0.0) as usize;
+            let first = dict.get(b"First").and_then(Object::number).unwrap_or(0.0) as usize;
+            let mut header = Lexer::new(&data, 0);
+            for _ in 0..count {
+                let (Some(Object::Number(number)), Some(Object::Number(offset))) = (header.object(), header.object())
+                else {
+                    break;
+                };
+                let mut lexer = Lexer::new(&data, first + offset as usize);
+                if let Some(object) = lexer.object() {
+                    self.objects.entry(number as u32).or_insert(object);
+                }
+            }
+        }
+    }
+
+    fn root(&self) -> Option<Object> {
+        let mut pos = 0;
+        let mut root = None;
+        while let Some(at) = find(&self.data, pos, b"trailer") {
+            pos = at + 7;
+            if let Some(trailer) = Lexer::new(&self.data, pos).object() {
+                if let Some(found) = trailer.dict().and_then(|trailer| trailer.get(b"Root")) {
+                    root = Some(found.clone());
+                }
+            }
+        }
+        let root = root.or_else(|| {
+            self.objects.values().rev().find_map(|object| match object {
+                Object::Stream(dict, _, _) if dict.get(b"Type").and_then(Object::name) == Some(b"XRef") => {
+                    dict.get(b"Root").cloned()
+                }
+                _ => None,
+            })
+        });
+        let root = root.map(|root| self.resolve(Some(&root)));
+        root.filter(|root| root.dict().is_some()).or_else(|| {
+            self.objects
+                .values()
+                .find(|object| {
+                    object.dict().and_then(|dict| dict.get(b"Type")).and_then(Object::name) == Some(b"Catalog")
+                })
+                .cloned()
+        })
+    }
+
+    /// Follow a reference, if it is one
+    pub fn resolve(&self, object: Option<&Object>) -> Object {
+        let mut object = object.cloned().unwrap_or(Object::Null);
+        for _ in 0..MAX_DEPTH {
+            match object {
+                Object::Ref(number) => object = self.objects.get(&number).cloned().unwrap_or(Object::Null),
+                _ => break,
+            }
+        }
+        object
+    }
+
+    fn number(&self, object: Option<&Object>) -> Option<f32> {
+        self.resolve(object).number()
+    }
+
+    fn collect_pages(&mut self, node: Object, resources: &Dict, media_box: [f32; 4], depth: usize) {
+        let Some(dict) = node.dict().cloned() else {
+            return;
+        };
+        if depth > MAX_DEPTH {
+            return;
+        }
+        let resources = match self.resolve(dict.get(b"Resources")) {
+            Object::Dict(own) => own,
+            _ => resources.clone(),
+        };
+        let media_box = match self.resolve(dict.get(b"MediaBox")) {
+            Object::Array(corners) if corners.len() == 4 => {
+                let mut found = [0.0; 4];
+                for (value, corner) in found.iter_mut().zip(&corners) {
+                    *value = self.number(Some(corner)).unwrap_or(0.0);
+                }
+                found
+            }
+            _ => media_box,
+        };
+        match self.resolve(dict.get(b"Kids")) {
+            Object::Array(kids) => {
+                for kid in kids {
+                    let kid = self.resolve(Some(&kid));
+                    self.collect_pages(kid, &resources, media_box, depth + 1);
+                }
+            }
+            _ => self.pages.push(Page { dict, resources, media_box }),
+        }
+    }
+
+    /// A stream's data with its filters undone
+    pub fn stream_data(&self, stream: &Object) -> Result<Vec<u8>, &'static str> {
+        let Object::Stream(dict, start, end) = stream else {
+            return Err("pdf: not a stream");
+        };
+        let mut data = self.data.get(*start..*end).ok_or("pdf: stream past the end")?.to_vec();
+        let filters = match self.resolve(dict.get(b"Filter")) {
+            Object::Name(name) => vec![name],
+            Object::Array(names) => {
+                names.iter().filter_map(|name| self.resolve(Some(name)).name().map(<[u8]>::to_vec)).collect()
+            }
+            _ => Vec::new(),
+        };
+        let parms = self.resolve(dict.get(b"DecodeParms"));
+        for (i, filter) in filters.iter().enumerate() {
+            let parms = match &parms {
+                Object::Array(each) => each.get(i).map(|parms| self.resolve(Some(parms))),
+                other => Some(other.clone()),
+            };
+            data = match filter.as_slice() {
+                b"FlateDecode" | b"Fl" => unpredict(&inflate(&data)?, parms.as_ref().and_then(Object::dict)),
+                b"ASCIIHexDecode" | b"AHx" => ascii_hex(&data),
+                b"ASCII85Decode" | b"A85" => ascii85(&data),
+                _ => return Err("pdf: unsupported filter"),
+            };
+        }
+        Ok(data)
+    }
+
+    // A page's content streams, joined
+    fn contents(&self, page: &Page) -> Vec<u8> {
+        let streams = match self.resolve(page.dict.get(b"Contents")) {
+            Object::Array(streams) => streams.iter().map(|stream| self.resolve(Some(stream))).collect(),
+            stream => vec![stream],
+        };
+        let mut contents = Vec::new();
+        for stream in streams {
+            match self.stream_data(&stream) {
+                Ok(data) => contents.extend_from_slice(&data),
+                Err(e) => crate::serial_println!("print: {}", e),
+            }
+            contents.push(b'\n');
+        }
+        contents
+    }
+}
+
+impl Source for Document {
+    fn next_page(&mut self, canvas: &mut Canvas) -> Result<bool, &'static str> {
+        let Some(page) = self.pages.get(self.next).cloned() else {
+            return Ok(false);
+        };
+        self.next += 1;
+        let [x0, y0, _, _] = page.media_box;
+        canvas.concat(Matrix::translate(-x0, -y0));
+        let contents = self.contents(&page);
+        let mut renderer = Renderer::new(self, canvas);
+        renderer.run(&contents, &page.resources, 0);
+        Ok(true)
+    }
+
+    fn page_count(&self) -> Option<usize> {
+        Some(self.pages.len())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Font {
+    first_char: u32,
+    widths: Vec<f32>,
+    missing_width: f32,
+    // Two-byte codes naming glyphs rather than characters
+    composite: bool,
+}
+
+impl Font {
+    fn width(&self, code: u32) -> f32 {
+        if self.composite {
+            return self.missing_width;
+        }
+        code.checked_sub(self.first_char)
+            .and_then(|index| self.widths.get(index as usize))
+            .copied()
+            .unwrap_or(self.missing_width)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TextState {
+    char_spacing: f32,
+    word_spacing: f32,
+    scale: f32,
+    leading: f32,
+    rise: f32,
+    size: f32,
+    mode: u32,
+    font: Option<Font>,
+}
+
+struct Renderer<'a> {
+    document: &'a Document,
+    canvas: &'a mut Canvas,
+    text: TextState,
+    // Text matrices live outside the graphics state in PDF
+    text_matrix: Matrix,
+    line_matrix: Matrix,
+    saved_text: Vec<TextState>,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(document: &'a Document, canvas: &'a mut Canvas) -> Renderer<'a> {
+        Renderer {
+            document,
+            canvas,
+            text: TextState {
+                char_spacing: 0.0,
+                word_spacing: 0.0,
+                scale: 1.0,
+                leading: 0.0,
+                rise: 0.0,
+                size: 12.0,
+                mode: 0,
+                font: None,
+            },
+            text_matrix: Matrix::IDENTITY,
+            line_matrix: Matrix::IDENTITY,
+            saved_text: Vec::new(),
+        }
+    }
+
+    fn resource(&self, resources: &Dict, category: &[u8], name: &[u8]) -> Object {
+        match self.document.resolve(resources.get(category)) {
+            Object::Dict(entries) => self.document.resolve(entries.get(name)),
+            _ => Object::Null,
+        }
+    }
+
+    fn load_font(&self, resources: &Dict, name: &[u8]) -> Option<Font> {
+        let document = self.document;
+        let font = self.resource(resources, b"Font", name);
+        let dict = font.dict()?;
+        if dict.get(b"Subtype").and_then(Object::name) == Some(b"Type0") {
+            let descendant = match document.resolve(dict.get(b"DescendantFonts")) {
+                Object::Array(fonts) => document.resolve(fonts.first()),
+                _ => Object::Null,
+            };
+            let default = descendant.dict().and_then(|d| document.number(d.get(b"DW"))).unwrap_or(1000.0);
+            return Some(Font { first_char: 0, widths: Vec::new(), missing_width: default, composite: true });
+        }
+        let widths = match document.resolve(dict.get(b"Widths")) {
+            Object::Array(widths) => widths.iter().map(|width| document.number(Some(width)).unwrap_or(0.0)).collect(),
+            _ => Vec::new(),
+        };
+        let descriptor = document.resolve(dict.get(b"FontDescriptor"));
+        let missing_width = descriptor
+            .dict()
+            .and_then(|descriptor| document.number(descriptor.get(b"MissingWidth")))
+            .filter(|&width| width > 0.0)
+            .unwrap_or(DEFAULT_WIDTH);
+        Some(Font {
+            first_char: document.number(dict.get(b"FirstChar")).unwrap_or(0.0) as u32,
+            widths,
+            missing_width,
+            composite: false,
+        })
+    }
+
+    fn show(&mut self, string: &[u8]) {
+        let font = self.text.font.clone().unwrap_or(Font {
+            first_char: 0,
+            widths: Vec::new(),
+            missing_width: DEFAULT_WIDTH,
+            composite: false,
+        });
+        let text = &self.text;
+        let codes: Vec<u32> = if font.composite {
+            string.chunks(2).map(|pair| pair.iter().fold(0, |code, &byte| code << 8 | byte as u32)).collect()
+        } else {
+            string.iter().map(|&byte| byte as u32).collect()
+        };
+        let glyph_space = Matrix([text.size * text.scale, 0.0, 0.0, text.size, 0.0, text.rise]);
+        let visible = text.mode != 3 && !font.composite;
+        for code in codes {
+            let width = font.width(code) / 1000.0;
+            if visible && (0x21..0x7F).contains(&code) {
+                let to_page = glyph_space.then(self.text_matrix);
+                self.canvas.glyph(code as u8 as char, to_page, width);
+            }
+            let mut advance = width * self.text.size + self.text.char_spacing;
+            if code == 32 && !font.composite {
+                advance += self.text.word_spacing;
+            }
+            self.text_matrix = Matrix::translate(advance * self.text.scale, 0.0).then(self.text_matrix);
+        }
+    }
+
+    fn next_line(&mut self, tx: f32, ty: f32) {
+        self.line_matrix = Matrix::translate(tx, ty).then(self.line_matrix);
+        self.text_matrix = self.line_matrix;
+    }
+
+    fn color(operands: &[Object]) -> Option<u8> {
+        let values: Vec<f32> = operands.iter().filter_map(Object::number).collect();
+        match values.as_slice() {
+            [gray] => Some(gray_of_level(*gray)),
+            [r, g, b] => Some(gray_of_rgb(*r, *g, *b)),
+            [c, m, y, k] => Some(gray_of_cmyk(*c, *m, *y, *k)),
+            _ => None,
+        }
+    }
+
+    fn draw_xobject(&mut self, resources: &Dict, name: &[u8], depth: usize) {
+        let xobject = self.resource(resources, b"XObject", name);
+        let Some(dict) = xobject.dict() else {
+            return;
+        };
+        match dict.get(b"Subtype").and_then(Object::name) {
+            Some(b"Form") if depth < MAX_DEPTH => {
+                let Ok(contents) = self.document.stream_data(&xobject) else {
+                    return;
+                };
+                let own = match self.document.resolve(dict.get(b"Resources")) {
+                    Object::Dict(own) => own,
+                    _ => resources.clone(),
+                };
+                self.canvas.save();
+                if let Object::Array(values) = self.document.resolve(dict.get(b"Matrix")) {
+                    if values.len() == 6 {
+                        let mut m = [0.0; 6];
+                        for (slot, value) in m.iter_mut().zip(&values) {
+                            *slot = value.number().unwrap_or(0.0);
+                        }
+                        self.canvas.concat(Matrix(m));
+                    }
+                }
+                self.run(&contents, &own, depth + 1);
+                self.canvas.restore();
+            }
+            Some(b"Image") => self.draw_image(&xobject, dict),
+            _ => {}
+        }
+    }
+
+    fn draw_image(&mut self, image: &Object, dict: &Dict) {
+        let document = self.document;
+        let width = document.number(dict.get(b"Width")).unwrap_or(0.0) as usize;
+        let height = document.number(dict.get(b"Height")).unwrap_or(0.0) as usize;
+        let mask = matches!(document.resolve(dict.get(b"ImageMask")), Object::Bool(true));
+        let bits = if mask { 1 } else { document.number(dict.get(b"BitsPerComponent")).unwrap_or(8.0) as usize };
+        let Ok(data) = document.stream_data(image) else {
+            return;
+        };
+        if width == 0 || height == 0 || !(bits == 1 || bits == 8) {
+            return;
+        }
+        let row_bits = data.len() * 8 / height;
+        let components = match row_bits / (width * bits) {
+            _ if mask => 1,
+            3 => 3,
+            4.. => 4,
+            _ => 1,
+        };
+        let row_length = (width * components * bits).div_ceil(8);
+        let mut samples = Vec::with_capacity(width * height);
+        for row in data.chunks(row_length).take(height) {
+            for x in 0..width {
+                let sample = |c: usize| -> f32 {
+                    let index = x * components + c;
+                    if bits == 8 {
+                        row.get(index).copied().unwrap_or(255) as f32 / 255.0
+                    } else {
+                        row.get(index / 8).map_or(1.0, |byte| (byte >> (7 - index % 8) & 1) as f32)
+                    }
+                };
+                samples.push(match components {
+                    1 => gray_of_level(sample(0)),
+                    3 => gray_of_rgb(sample(0), sample(1), sample(2)),
+                    _ => gray_of_cmyk(sample(0), sample(1), sample(2), sample(3)),
+                });
+            }
+        }
+        self.canvas.image(width, samples.len() / width, &samples, mask);
+    }
+
+    fn run(&mut self, contents: &[u8], resources: &Dict, depth: usize) {
+        let mut lexer = Lexer::new(contents, 0);
+        let mut operands: Vec<Object> = Vec::new();
+        while let Some(token) = lexer.token() {
+            let operator = match token {
+                Token::Object(object) => {
+                    operands.push(object);
+                    continue;
+                }
+                Token::Keyword(operator) => operator,
+                _ => continue,
+            };
+            let n = |i: usize| operands.get(i).and_then(Object::number).unwrap_or(0.0);
+            match operator.as_slice() {
+                b"q" => {
+                    self.canvas.save();
+                    self.saved_text.push(self.text.clone());
+                }
+                b"Q" => {
+                    self.canvas.restore();
+                    if let Some(text) = self.saved_text.pop() {
+                        self.text = text;
+                    }
+                }
+                b"cm" => self.canvas.concat(Matrix([n(0), n(1), n(2), n(3), n(4), n(5)])),
+                b"w" => self.canvas.state.line_width = n(0),
+                b"m" => self.canvas.move_to(n(0), n(1)),
+                b"l" => self.canvas.line_to(n(0), n(1)),
+                b"c" => self.canvas.curve_to(n(0), n(1), n(2), n(3), n(4), n(5)),
+                b"v" => {
+                    if let Some((x, y)) = self.canvas.current_point() {
+                        self.canvas.curve_to(x, y, n(0), n(1), n(2), n(3));
+                    }
+                }
+                b"y" => self.canvas.curve_to(n(0), n(1), n(2), n(3), n(2), n(3)),
+                b"h" => self.canvas.close_path(),
+                b"re" => self.canvas.rect(n(0), n(1), n(2), n(3)),
+                b"S" => self.canvas.stroke(),
+                b"s" => {
+                    self.canvas.close_path();
+                    self.canvas.stroke();
+                }
+                b"f" | b"F" | b"f*" | b"B" | b"B*" | b"b" | b"b*" => {
+                    let even_odd = operator.ends_with(b"*");
+                    let stroke = operator[0] != b'f' && operator[0] != b'F';
+                    if operator[0] == b'b' {
+                        self.canvas.close_path();
+                    }
+                    let rule = if even_odd { FillRule::EvenOdd } else { FillRule::NonZero };
+                    if stroke {
+                        self.canvas.fill_and_stroke(rule);
+                    } else {
+                        self.canvas.fill(rule);
+                    }
+                }
+                b"n" => self.canvas.new_path(),
+                b"W" | b"W*" => self.canvas.clip(),
+                b"g" | b"rg" | b"k" | b"sc" | b"scn" => {
+                    if let Some(gray) = Self::color(&operands) {
+                        self.canvas.state.fill = gray;
+                    }
+                }
+                b"G" | b"RG" | b"K" | b"SC" | b"SCN" => {
+                    if let Some(gray) = Self::color(&operands) {
+                        self.canvas.state.stroke = gray;
+                    }
+                }
+                // A new colour space starts at its initial colour, black
+                b"cs" => self.canvas.state.fill = 0,
+                b"CS" => self.canvas.state.stroke = 0,
+                b"BT" => {
+                    self.text_matrix = Matrix::IDENTITY;
+                    self.line_matrix = Matrix::IDENTITY;
+                }
+                b"Tc" => self.text.char_spacing = n(0),
+                b"Tw" => self.text.word_spacing = n(0),
+                b"Tz" => self.text.scale = n(0) / 100.0,
+                b"TL" => self.text.leading = n(0),
+                b"Ts" => self.text.rise = n(0),
+                b"Tr" => self.text.mode = n(0) as u32,
+                b"Tf" => {
+                    self.text.size = n(1);
+                    if let Some(name) = operands.first().and_then(Object::name) {
+                        self.text.font = self.load_font(resources, name);
+                    }
+                }
+                b"Td" => self.next_line(n(0), n(1)),
+                b"TD" => {
+                    self.text.leading = -n(1);
+                    self.next_line(n(0), n(1));
+                }
+                b"Tm" => {
+                    self.line_matrix = Matrix([n(0), n(1), n(2), n(3), n(4), n(5)]);
+                    self.text_matrix = self.line_matrix;
+                }
+                b"T*" => self.next_line(0.0, -self.text.leading),
+                b"Tj" => {
+                    if let Some(Object::String(string)) = operands.first() {
+                        self.show(&string.clone());
+                    }
+                }
+                b"'" | b"\"" => {
+                    if operator == b"\"" {
+                        self.text.word_spacing = n(0);
+                        self.text.char_spacing = n(1);
+                    }
+                    self.next_line(0.0, -self.text.leading);
+                    if let Some(Object::String(string)) = operands.last() {
+                        self.show(&string.clone());
+                    }
+                }
+                b"TJ" => {
+                    let items = operands.first().and_then(Object::array).map(<[Object]>::to_vec).unwrap_or_default();
+                    for item in items {
+                        match item {
+                            Object::String(string) => self.show(&string),
+                            Object::Number(adjust) => {
+                                let shift = -adjust / 1000.0 * self.text.size * self.text.scale;
+                                self.text_matrix = Matrix::translate(shift, 0.0).then(self.text_matrix);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                b"Do" => {
+                    if let Some(name) = operands.first().and_then(Object::name) {
+                        let name = name.to_vec();
+                        self.draw_xobject(resources, &name, depth);
+                    }
+                }
+                // Inline image data is binary: step over it to the EI
+                b"ID" => {
+                    let after = find(contents, lexer.pos, b"EI").map_or(contents.len(), |at| at + 2);
+                    lexer.pos = after;
+                }
+                _ => {}
+            }
+            operands.clear();
+        }
+    }
+}
@@ -0,0 +1,822 @@
+// Drawing PostScript pages
+//
+// A small interpreter for the PostScript that applications and printer
+// drivers write: the operand, dictionary and execution stacks, procedures
+// and loops, arithmetic and the usual stack and dictionary operators,
+// paths, colours, transforms and `show`. Execution is kept on an explicit
+// stack so that it can stop at a `showpage` and take up again for the next
+// page.
+//
+// Fonts are all drawn as the console font, a glyph 0.6 em wide, and
+// operators outside the subset are skipped, which is enough for most
+// DSC-conforming output but not for programs built on `stopped`,
+// `forall` or the resource machinery.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+use spin::Mutex;
+
+use super::pdf::{parse_number, Lexer};
+use super::{gray_of_cmyk, gray_of_level, gray_of_rgb, Canvas, FillRule, Matrix, Source};
+
+// Glyph advance in ems
+const ADVANCE: f32 = 0.6;
+// Operators run before a page is given up on, so a program that loops
+// forever cannot hold the spooler
+const MAX_OPERATIONS: usize = 5_000_000;
+
+type Procedure = Arc<Vec<Value>>;
+type Dictionary = Arc<Mutex<BTreeMap<Vec<u8>, Value>>>;
+
+#[derive(Clone)]
+enum Value {
+    Null,
+    Number(f32),
+    Bool(bool),
+    String(Vec<u8>),
+    /// A literal name, `/name`
+    Name(Vec<u8>),
+    /// A name to look up and run
+    Exec(Vec<u8>),
+    Array(Procedure),
+    Procedure(Procedure),
+    Dict(Dictionary),
+    /// A font at a size, in points
+    Font(f32),
+    Mark,
+}
+
+enum Frame {
+    // The document itself
+    File,
+    Procedure(Procedure, usize),
+    Repeat(Procedure, u32),
+    For { procedure: Procedure, next: f32, step: f32, limit: f32 },
+    Loop(Procedure),
+}
+
+fn new_dict() -> Dictionary {
+    Arc::new(Mutex::new(BTreeMap::new()))
+}
+
+fn sin_cos(degrees: f32) -> (f32, f32) {
+    let turns = degrees / 360.0;
+    let mut x = (turns - super::floor(turns + 0.5)) * 2.0 * PI;
+    // Into [-pi/2, pi/2], where the series converge fast
+    let mut sign = 1.0;
+    if x > PI / 2.0 {
+        x = PI - x;
+        sign = -1.0;
+    } else if x < -PI / 2.0 {
+        x = -PI - x;
+        sign = -1.0;
+    }
+    let x2 = x * x;
+    let sin = x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0))));
+    let cos = 1.0 - x2 / 2.0 * (1.0 - x2 / 12.0 * (1.0 - x2 / 30.0 * (1.0 - x2 / 56.0)));
+    (sin, cos * sign)
+}
+
+// A PostScript number: 12, -3.5, .5, 1e3 or 16#FF
+fn number(word: &[u8]) -> Option<f32> {
+    if let Some(hash) = word.iter().position(|&byte| byte == b'#') {
+        let radix: u32 = core::str::from_utf8(&word[..hash]).ok()?.parse().ok()?;
+        let digits = core::str::from_utf8(&word[hash + 1..]).ok()?;
+        return (2..=36).contains(&radix).then(|| u32::from_str_radix(digits, radix).ok()).flatten().map(|n| n as f32);
+    }
+    let exponent_at = word.iter().position(|&byte| byte == b'e' || byte == b'E');
+    let Some(at) = exponent_at else {
+        return parse_number(word);
+    };
+    let mantissa = parse_number(&word[..at])?;
+    let exponent: i32 = core::str::from_utf8(&word[at + 1..]).ok()?.parse().ok()?;
+    let mut value = mantissa;
+    for _ in 0..exponent.unsigned_abs().min(38) {
+        value = if exponent > 0 { value * 10.0 } else { value / 10.0 };
+    }
+    Some(value)
+}
+
+pub struct Interpreter {
+    data: Vec<u8>,
+    pos: usize,
+    operands: Vec<Value>,
+    dicts: Vec<Dictionary>,
+    frames: Vec<Frame>,
+    font: f32,
+    finished: bool,
+}
+
+impl Interpreter {
+    pub fn new(data: Vec<u8>) -> Interpreter {
+        Interpreter {
+            data,
+            pos: 0,
+            operands: Vec::new(),
+            dicts: vec![new_dict()],
+            frames: vec![Frame::File],
+            font: 10.0,
+            finished: false,
+        }
+    }
+
+    // The next value from the document, a procedure read whole
+    fn scan(&mut self) -> Option<Value> {
+        let mut lexer = Lexer::new(&self.data, self.pos);
+        let value = Self::scan_value(&mut lexer);
+        self.pos = lexer.pos;
+        value
+    }
+
+    fn scan_value(lexer: &mut Lexer) -> Option<Value> {
+        lexer.skip_whitespace();
+        let byte = lexer.peek()?;
+        lexer.pos += 1;
+        let value = match byte {
+            b'(' => Value::String(lexer.literal_string()),
+            b'<' if lexer.peek() == Some(b'<') => {
+                lexer.pos += 1;
+                Value::Exec(b"<<".to_vec())
+            }
+            b'>' if lexer.peek() == Some(b'>') => {
+                lexer.pos += 1;
+                Value::Exec(b">>".to_vec())
+            }
+            b'<' => Value::String(lexer.hex_string()),
+            b'[' => Value::Exec(b"[".to_vec()),
+            b']' => Value::Exec(b"]".to_vec()),
+            b'{' => {
+                let mut body = Vec::new();
+                loop {
+                    lexer.skip_whitespace();
+                    match lexer.peek() {
+                        None => break,
+                        Some(b'}') => {
+                            lexer.pos += 1;
+                            break;
+                        }
+                        _ => body.extend(Self::scan_value(lexer)),
+                    }
+                }
+                Value::Procedure(Arc::new(body))
+            }
+            b'/' => {
+                // `//name` is looked up as it is read in PostScript; here, when run
+                if lexer.peek() == Some(b'/') {
+                    lexer.pos += 1;
+                    Value::Exec(lexer.regular().to_vec())
+                } else {
+                    Value::Name(lexer.regular().to_vec())
+                }
+            }
+            b'}' | b')' | b'>' => return Self::scan_value(lexer),
+            _ => {
+                lexer.pos -= 1;
+                let word = lexer.regular();
+                if word.is_empty() {
+                    lexer.pos += 1;
+                    return Self::scan_value(lexer);
+                }
+                number(word).map_or_else(|| Value::Exec(word.to_vec()), Value::Number)
+            }
+        };
+        Some(value)
+    }
+
+    fn pop(&mut self) -> Result<Value, &'static str> {
+        self.operands.pop().ok_or("postscript: stack underflow")
+    }
+
+    fn pop_number(&mut self) -> Result<f32, &'static str> {
+        match self.pop()? {
+            Value::Number(n) => Ok(n),
+            _ => Err("postscript: number expected"),
+        }
+    }
+
+    fn pop_numbers<const N: usize>(&mut self) -> Result<[f32; N], &'static str> {
+        let mut values = [0.0; N];
+        for value in values.iter_mut().rev() {
+            *value = self.pop_number()?;
+        }
+        Ok(values)
+    }
+
+    fn pop_procedure(&mut self) -> Result<Procedure, &'static str> {
+        match self.pop()? {
+            Value::Procedure(procedure) | Value::Array(procedure) => Ok(procedure),
+            _ => Err("postscript: procedure expected"),
+        }
+    }
+
+    fn pop_matrix(&mut self) -> Result<Matrix, &'static str> {
+        let Value::Array(items) = self.pop()? else {
+            return Err("postscript: matrix expected");
+        };
+        let mut m = [0.0; 6];
+        for (slot, item) in m.iter_mut().zip(items.iter()) {
+            if let Value::Number(n) = item {
+                *slot = *n;
+            }
+        }
+        Ok(Matrix(m))
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<Value> {
+        self.dicts.iter().rev().find_map(|dict| dict.lock().get(name).cloned())
+    }
+
+    fn run_value(&mut self, value: Value, canvas: &mut Canvas) -> Result<bool, &'static str> {
+        match value {
+            Value::Exec(name) => match self.lookup(&name) {
+                Some(Value::Procedure(procedure)) => {
+                    self.frames.push(Frame::Procedure(procedure, 0));
+                    Ok(false)
+                }
+                Some(Value::Exec(builtin)) => self.operator(&builtin, canvas),
+                Some(value) => {
+                    self.operands.push(value);
+                    Ok(false)
+                }
+                None => self.operator(&name, canvas),
+            },
+            value => {
+                self.operands.push(value);
+                Ok(false)
+            }
+        }
+    }
+
+    // The next value to run, from whichever frame is on top
+    fn next_value(&mut self) -> Option<Value> {
+        loop {
+            let frame = self.frames.last_mut()?;
+            match frame {
+                Frame::File => match self.scan() {
+                    Some(value) => return Some(value),
+                    None => {
+                        self.frames.pop();
+                    }
+                },
+                Frame::Procedure(procedure, index) => match procedure.get(*index) {
+                    Some(value) => {
+                        *index += 1;
+                        return Some(value.clone());
+                    }
+                    None => {
+                        self.frames.pop();
+                    }
+                },
+                Frame::Repeat(procedure, count) => {
+                    if *count == 0 {
+                        self.frames.pop();
+                    } else {
+                        *count -= 1;
+                        let procedure = procedure.clone();
+                        self.frames.push(Frame::Procedure(procedure, 0));
+                    }
+                }
+                Frame::For { procedure, next, step, limit } => {
+                    let done = if *step >= 0.0 { *next > *limit } else { *next < *limit };
+                    if done {
+                        self.frames.pop();
+                    } else {
+                        let value = *next;
+                        *next += *step;
+                        let procedure = procedure.clone();
+                        self.operands.push(Value::Number(value));
+                        self.frames.push(Frame::Procedure(procedure, 0));
+                    }
+                }
+                Frame::Loop(procedure) => {
+                    let procedure = procedure.clone();
+                    self.frames.push(Frame::Procedure(procedure, 0));
+                }
+            }
+        }
+    }
+
+    // Leave the innermost loop
+    fn exit(&mut self) {
+        while let Some(frame) = self.frames.pop() {
+            if matches!(frame, Frame::Repeat(..) | Frame::For { .. } | Frame::Loop(_)) {
+                break;
+            }
+            if matches!(frame, Frame::File) {
+                self.frames.push(frame);
+                break;
+            }
+        }
+    }
+
+    fn show(&mut self, string: &[u8], canvas: &mut Canvas) -> Result<(), &'static str> {
+        let (mut x, y) = canvas.current_point().ok_or("postscript: show with no current point")?;
+        for &byte in string {
+            canvas.glyph(byte as char, Matrix([self.font, 0.0, 0.0, self.font, x, y]), ADVANCE);
+            x += ADVANCE * self.font;
+        }
+        canvas.move_to(x, y);
+        Ok(())
+    }
+
+    // Run a built-in operator; true once a page is finished
+    fn operator(&mut self, name: &[u8], canvas: &mut Canvas) -> Result<bool, &'static str> {
+        match name {
+            // Stack
+            b"pop" => {
+                self.pop()?;
+            }
+            b"exch" => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.operands.push(b);
+                self.operands.push(a);
+            }
+            b"dup" => {
+                let top = self.operands.last().cloned().ok_or("postscript: stack underflow")?;
+                self.operands.push(top);
+            }
+            b"copy" => {
+                let n = self.pop_number()? as usize;
+                let from = self.operands.len().checked_sub(n).ok_or("postscript: stack underflow")?;
+                self.operands.extend_from_within(from..);
+            }
+            b"index" => {
+                let n = self.pop_number()? as usize;
+                let at = self.operands.len().checked_sub(n + 1).ok_or("postscript: stack underflow")?;
+                self.operands.push(self.operands[at].clone());
+            }
+            b"roll" => {
+                let [n, j] = self.pop_numbers::<2>()?;
+                let n = n as usize;
+                let from = self.operands.len().checked_sub(n).ok_or("postscript: stack underflow")?;
+                if n > 0 {
+                    let shift = (j as i64).rem_euclid(n as i64) as usize;
+                    self.operands[from..].rotate_right(shift);
+                }
+            }
+            b"clear" => self.operands.clear(),
+            b"count" => self.operands.push(Value::Number(self.operands.len() as f32)),
+            b"mark" | b"[" | b"<<" => self.operands.push(Value::Mark),
+            b"cleartomark" | b"]" | b">>" => {
+                let at = self.operands.iter().rposition(|value| matches!(value, Value::Mark));
+                let at = at.ok_or("postscript: no mark")?;
+                let items: Vec<Value> = self.operands.drain(at..).skip(1).collect();
+                match name {
+                    b"]" => self.operands.push(Value::Array(Arc::new(items))),
+                    b">>" => {
+                        let dict = new_dict();
+                        for pair in items.chunks(2) {
+                            if let [Value::Name(key), value] = pair {
+                                dict.lock().insert(key.clone(), value.clone());
+                            }
+                        }
+                        self.operands.push(Value::Dict(dict));
+                    }
+                    _ => {}
+                }
+            }
+            b"counttomark" => {
+                let at = self.operands.iter().rposition(|value| matches!(value, Value::Mark));
+                let at = at.ok_or("postscript: no mark")?;
+                self.operands.push(Value::Number((self.operands.len() - at - 1) as f32));
+            }
+
+            // Arithmetic and logic
+            b"add" | b"sub" | b"mul" | b"div" | b"idiv" | b"mod" | b"max" | b"min" => {
+                let [a, b] = self.pop_numbers::<2>()?;
+                let result = match name {
+                    b"add" => a + b,
+                    b"sub" => a - b,
+                    b"mul" => a * b,
+                    b"div" if b != 0.0 => a / b,
+                    b"idiv" if b as i64 != 0 => (a as i64 / b as i64) as f32,
+                    b"mod" if b as i64 != 0 => (a as i64 % b as i64) as f32,
+                    b"max" => a.max(b),
+                    b"min" => a.min(b),
+                    _ => return Err("postscript: division by zero"),
+                };
+                self.operands.push(Value::Number(result));
+            }
+            b"neg" | b"abs" | b"cvi" | b"cvr" | b"floor" | b"ceiling" | b"round" | b"truncate" | b"sqrt" => {
+                let a = self.pop_number()?;
+                let result = match name {
+                    b"neg" => -a,
+                    b"abs" if a < 0.0 => -a,
+                    b"cvi" | b"truncate" => a as i64 as f32,
+                    b"floor" => super::floor(a),
+                    b"ceiling" => -super::floor(-a),
+                    b"round" => super::floor(a + 0.5),
+                    b"sqrt" => super::sqrt(a),
+                    _ => a,
+                };
+                self.operands.push(Value::Number(result));
+            }
+            b"eq" | b"ne" => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                let equal = match (&a, &b) {
+                    (Value::Number(a), Value::Number(b)) => a == b,
+                    (Value::Bool(a), Value::Bool(b)) => a == b,
+                    (
+                        Value::Name(a) | Value::Exec(a) | Value::String(a),
+                        Value::Name(b) | Value::Exec(b) | Value::String(b),
+                    ) => {
+                        a == b
+                    }
+                    (Value::Null, Value::Null) => true,
+                    _ => false,
+                };
+                self.operands.push(Value::Bool(equal == (name == b"eq")));
+            }
+            b"gt" | b"lt" | b"ge" | b"le" => {
+                let [a, b] = self.pop_numbers::<2>()?;
+                let result = match name {
+                    b"gt" => a > b,
+                    b"lt" => a < b,
+                    b"ge" => a >= b,
+                    _ => a <= b,
+                };
+                self.operands.push(Value::Bool(result));
+            }
+            b"not" => match self.pop()? {
+                Value::Bool(b) => self.operands.push(Value::Bool(!b)),
+                Value::Number(n) => self.operands.push(Value::Number(!(n as i32) as f32)),
+                _ => return Err("postscript: not of a non-boolean"),
+            },
+            b"and" | b"or" | b"xor" => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                let (Value::Bool(a), Value::Bool(b)) = (a, b) else {
+                    return Err("postscript: logic on non-booleans");
+                };
+                let result = match name {
+                    b"and" => a && b,
+                    b"or" => a || b,
+                    _ => a != b,
+                };
+                self.operands.push(Value::Bool(result));
+            }
+            b"true" => self.operands.push(Value::Bool(true)),
+            b"false" => self.operands.push(Value::Bool(false)),
+            b"null" => self.operands.push(Value::Null),
+
+            // Control
+            b"exec" => {
+                let value = self.pop()?;
+                match value {
+                    Value::Procedure(procedure) => self.frames.push(Frame::Procedure(procedure, 0)),
+                    value => return self.run_value(value, canvas),
+                }
+            }
+            b"if" => {
+                let procedure = self.pop_procedure()?;
+                if let Value::Bool(true) = self.pop()? {
+                    self.frames.push(Frame::Procedure(procedure, 0));
+                }
+            }
+            b"ifelse" => {
+                let (otherwise, then) = (self.pop_procedure()?, self.pop_procedure()?);
+                let condition = matches!(self.pop()?, Value::Bool(true));
+                self.frames.push(Frame::Procedure(if condition { then } else { otherwise }, 0));
+            }
+            b"repeat" => {
+                let procedure = self.pop_procedure()?;
+                let count = self.pop_number()?.max(0.0) as u32;
+                if !procedure.is_empty() {
+                    self.frames.push(Frame::Repeat(procedure, count));
+                }
+            }
+            b"for" => {
+                let procedure = self.pop_procedure()?;
+                let [next, step, limit] = self.pop_numbers::<3>()?;
+                if !procedure.is_empty() && step != 0.0 {
+                    self.frames.push(Frame::For { procedure, next, step, limit });
+                }
+            }
+            b"loop" => {
+                let procedure = self.pop_procedure()?;
+                if !procedure.is_empty() {
+                    self.frames.push(Frame::Loop(procedure));
+                }
+            }
+            b"exit" => self.exit(),
+            b"quit" => {
+                self.frames.clear();
+            }
+
+            // Dictionaries, arrays and strings
+            b"dict" => {
+                self.pop_number()?;
+                self.operands.push(Value::Dict(new_dict()));
+            }
+            b"begin" => match self.pop()? {
+                Value::Dict(dict) => self.dicts.push(dict),
+                _ => return Err("postscript: begin of a non-dictionary"),
+            },
+            b"end" => {
+                if self.dicts.len() > 1 {
+                    self.dicts.pop();
+                }
+            }
+            b"def" => {
+                let value = self.pop()?;
+                let key = match self.pop()? {
+                    Value::Name(key) | Value::String(key) => key,
+                    _ => return Err("postscript: def of a non-name"),
+                };
+                self.dicts.last().unwrap().lock().insert(key, value);
+            }
+            b"load" => {
+                let key = match self.pop()? {
+                    Value::Name(key) => key,
+                    _ => return Err("postscript: load of a non-name"),
+                };
+                let value = self.lookup(&key).unwrap_or(Value::Exec(key));
+                self.operands.push(value);
+            }
+            b"where" => {
+                let key = match self.pop()? {
+                    Value::Name(key) => key,
+                    _ => return Err("postscript: where of a non-name"),
+                };
+                match self.dicts.iter().rev().find(|dict| dict.lock().contains_key(&key)) {
+                    Some(dict) => {
+                        let dict = dict.clone();
+                        self.operands.push(Value::Dict(dict));
+                        self.operands.push(Value::Bool(true));
+                    }
+                    None => self.operands.push(Value::Bool(false)),
+                }
+            }
+            b"known" => {
+                let key = self.pop()?;
+                let dict = self.pop()?;
+                let known = match (dict, key) {
+                    (Value::Dict(dict), Value::Name(key)) => dict.lock().contains_key(&key),
+                    _ => false,
+                };
+                self.operands.push(Value::Bool(known));
+            }
+            b"currentdict" | b"userdict" | b"systemdict" | b"globaldict" | b"statusdict" => {
+                let dict = if name == b"currentdict" { self.dicts.last() } else { self.dicts.first() };
+                self.operands.push(Value::Dict(dict.unwrap().clone()));
+            }
+            b"bind" | b"readonly" | b"executeonly" | b"noaccess" => {}
+            b"cvx" => match self.pop()? {
+                Value::Array(items) => self.operands.push(Value::Procedure(items)),
+                Value::Name(name) => self.operands.push(Value::Exec(name)),
+                value => self.operands.push(value),
+            },
+            b"cvlit" => match self.pop()? {
+                Value::Procedure(items) => self.operands.push(Value::Array(items)),
+                value => self.operands.push(value),
+            },
+            b"array" => {
+                let n = self.pop_number()? as usize;
+                self.operands.push(Value::Array(Arc::new(vec![Value::Null; n.min(65536)])));
+            }
+            b"string" => {
+                let n = self.pop_number()? as usize;
+                self.operands.push(Value::String(vec![0; n.min(65536)]));
+            }
+            b"length" => {
+                let length = match self.pop()? {
+                    Value::String(s) | Value::Name(s) => s.len(),
+                    Value::Array(items) | Value::Procedure(items) => items.len(),
+                    Value::Dict(dict) => dict.lock().len(),
+                    _ => return Err("postscript: length of a non-composite"),
+                };
+                self.operands.push(Value::Number(length as f32));
+            }
+            b"get" => {
+                let key = self.pop()?;
+                let value = match (self.pop()?, key) {
+                    (Value::Array(items) | Value::Procedure(items), Value::Number(i)) => {
+                        items.get(i as usize).cloned().ok_or("postscript: index out of range")?
+                    }
+                    (Value::String(s), Value::Number(i)) => {
+                        Value::Number(*s.get(i as usize).ok_or("postscript: index out of range")? as f32)
+                    }
+                    (Value::Dict(dict), Value::Name(key)) => {
+                        dict.lock().get(&key).cloned().ok_or("postscript: undefined key")?
+                    }
+                    _ => return Err("postscript: get of the wrong types"),
+                };
+                self.operands.push(value);
+            }
+            b"put" => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                if let (Value::Dict(dict), Value::Name(key)) = (self.pop()?, key) {
+                    dict.lock().insert(key, value);
+                }
+            }
+            b"aload" => {
+                let items = self.pop_procedure()?;
+                self.operands.extend(items.iter().cloned());
+                self.operands.push(Value::Array(items));
+            }
+
+            // Graphics state
+            b"gsave" | b"save" => {
+                canvas.save();
+                if name == b"save" {
+                    self.operands.push(Value::Null);
+                }
+            }
+            b"grestore" | b"restore" => {
+                if name == b"restore" {
+                    self.pop()?;
+                }
+                canvas.restore();
+            }
+            b"setlinewidth" => canvas.state.line_width = self.pop_number()?,
+            b"setgray" => {
+                let gray = gray_of_level(self.pop_number()?);
+                canvas.state.fill = gray;
+                canvas.state.stroke = gray;
+            }
+            b"setrgbcolor" => {
+                let [r, g, b] = self.pop_numbers::<3>()?;
+                canvas.state.fill = gray_of_rgb(r, g, b);
+                canvas.state.stroke = canvas.state.fill;
+            }
+            b"setcmykcolor" => {
+                let [c, m, y, k] = self.pop_numbers::<4>()?;
+                canvas.state.fill = gray_of_cmyk(c, m, y, k);
+                canvas.state.stroke = canvas.state.fill;
+            }
+            b"setlinecap" | b"setlinejoin" | b"setmiterlimit" | b"setflat" | b"setoverprint" | b"setstrokeadjust" => {
+                self.pop()?;
+            }
+            b"setdash" => {
+                self.pop()?;
+                self.pop()?;
+            }
+            b"setpagedevice" | b"setcolorspace" => {
+                self.pop()?;
+            }
+            b"translate" | b"scale" => {
+                let [x, y] = self.pop_numbers::<2>()?;
+                canvas.concat(if name == b"translate" { Matrix::translate(x, y) } else { Matrix::scale(x, y) });
+            }
+            b"rotate" => {
+                let (sin, cos) = sin_cos(self.pop_number()?);
+                canvas.concat(Matrix([cos, sin, -sin, cos, 0.0, 0.0]));
+            }
+            b"concat" => {
+                let matrix = self.pop_matrix()?;
+                canvas.concat(matrix);
+            }
+            b"initgraphics" => canvas.clear_state(),
+
+            // Paths and painting
+            b"newpath" => canvas.new_path(),
+            b"moveto" | b"lineto" => {
+                let [x, y] = self.pop_numbers::<2>()?;
+                if name == b"moveto" {
+                    canvas.move_to(x, y);
+                } else {
+                    canvas.line_to(x, y);
+                }
+            }
+            b"rmoveto" | b"rlineto" => {
+                let [dx, dy] = self.pop_numbers::<2>()?;
+                let (x, y) = canvas.current_point().ok_or("postscript: no current point")?;
+                if name == b"rmoveto" {
+                    canvas.move_to(x + dx, y + dy);
+                } else {
+                    canvas.line_to(x + dx, y + dy);
+                }
+            }
+            b"curveto" => {
+                let [x1, y1, x2, y2, x3, y3] = self.pop_numbers::<6>()?;
+                canvas.curve_to(x1, y1, x2, y2, x3, y3);
+            }
+            b"rcurveto" => {
+                let [x1, y1, x2, y2, x3, y3] = self.pop_numbers::<6>()?;
+                let (x, y) = canvas.current_point().ok_or("postscript: no current point")?;
+                canvas.curve_to(x + x1, y + y1, x + x2, y + y2, x + x3, y + y3);
+            }
+            b"arc" | b"arcn" => {
+                let [x, y, r, start, end] = self.pop_numbers::<5>()?;
+                let mut sweep = end - start;
+                if name == b"arc" && sweep < 0.0 {
+                    sweep += 360.0;
+                } else if name == b"arcn" && sweep > 0.0 {
+                    sweep -= 360.0;
+                }
+                let steps = 32;
+                for i in 0..=steps {
+                    let (sin, cos) = sin_cos(start + sweep * i as f32 / steps as f32);
+                    if i == 0 && canvas.current_point().is_none() {
+                        canvas.move_to(x + r * cos, y + r * sin);
+                    } else {
+                        canvas.line_to(x + r * cos, y + r * sin);
+                    }
+                }
+            }
+            b"closepath" => canvas.close_path(),
+            b"currentpoint" => {
+                let (x, y) = canvas.current_point().ok_or("postscript: no current point")?;
+                self.operands.push(Value::Number(x));
+                self.operands.push(Value::Number(y));
+            }
+            b"fill" => canvas.fill(FillRule::NonZero),
+            b"eofill" => canvas.fill(FillRule::EvenOdd),
+            b"stroke" => canvas.stroke(),
+            b"clip" | b"eoclip" => canvas.clip(),
+            b"rectfill" | b"rectstroke" | b"rectclip" => {
+                let [x, y, w, h] = self.pop_numbers::<4>()?;
+                canvas.new_path();
+                canvas.rect(x, y, w, h);
+                match name {
+                    b"rectfill" => canvas.fill(FillRule::NonZero),
+                    b"rectstroke" => canvas.stroke(),
+                    _ => {
+                        canvas.clip();
+                        canvas.new_path();
+                    }
+                }
+            }
+            b"erasepage" => canvas.bitmap.bits.fill(0),
+            b"showpage" | b"copypage" => return Ok(true),
+
+            // Text
+            b"findfont" => {
+                self.pop()?;
+                self.operands.push(Value::Font(1.0));
+            }
+            b"scalefont" => {
+                let scale = self.pop_number()?;
+                match self.pop()? {
+                    Value::Font(size) => self.operands.push(Value::Font(size * scale)),
+                    _ => return Err("postscript: scalefont of a non-font"),
+                }
+            }
+            b"makefont" => {
+                let matrix = self.pop_matrix()?;
+                match self.pop()? {
+                    Value::Font(size) => self.operands.push(Value::Font(size * matrix.0[3])),
+                    _ => return Err("postscript: makefont of a non-font"),
+                }
+            }
+            b"setfont" => match self.pop()? {
+                Value::Font(size) => self.font = size,
+                _ => return Err("postscript: setfont of a non-font"),
+            },
+            b"selectfont" => {
+                let size = match self.pop()? {
+                    Value::Number(size) => size,
+                    Value::Array(matrix) => match matrix.get(3) {
+                        Some(Value::Number(size)) => *size,
+                        _ => self.font,
+                    },
+                    _ => self.font,
+                };
+                self.pop()?;
+                self.font = size;
+            }
+            b"show" => match self.pop()? {
+                Value::String(string) => self.show(&string, canvas)?,
+                _ => return Err("postscript: show of a non-string"),
+            },
+            b"stringwidth" => match self.pop()? {
+                Value::String(string) => {
+                    self.operands.push(Value::Number(string.len() as f32 * ADVANCE * self.font));
+                    self.operands.push(Value::Number(0.0));
+                }
+                _ => return Err("postscript: stringwidth of a non-string"),
+            },
+            _ => {}
+        }
+        Ok(false)
+    }
+}
+
+impl Source for Interpreter {
+    fn next_page(&mut self, canvas: &mut Canvas) -> Result<bool, &'static str> {
+        if self.finished {
+            return Ok(false);
+        }
+        for _ in 0..MAX_OPERATIONS {
+            let Some(value) = self.next_value() else {
+                // Marks left with no showpage after them still make a page
+                self.finished = true;
+                return Ok(!canvas.bitmap.is_blank());
+            };
+            match self.run_value(value, canvas) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => {
+                    self.finished = true;
+                    return Err(e);
+                }
+            }
+        }
+        self.finished = true;
+        Err("postscript: page takes too many operations")
+    }
+}
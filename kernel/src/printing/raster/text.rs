@@ -0,0 +1,74 @@
+// Plain text, set as a line printer would
+//
+// 10 point type on 12 point lines inside half-inch margins, tabs every 8
+// columns, long lines wrapped and a form feed starting a new page.
+// Characters outside ASCII print as `?`.
+
+use alloc::vec::Vec;
+
+use super::{Canvas, Matrix, Source};
+
+const SIZE: f32 = 10.0;
+const LEADING: f32 = 12.0;
+const MARGIN: f32 = 36.0;
+// Glyph advance in ems
+const ADVANCE: f32 = 0.6;
+const TAB: usize = 8;
+
+pub struct Text {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Text {
+    pub fn new(data: Vec<u8>) -> Text {
+        Text { data, pos: 0 }
+    }
+}
+
+impl Source for Text {
+    fn next_page(&mut self, canvas: &mut Canvas) -> Result<bool, &'static str> {
+        if self.pos >= self.data.len() {
+            return Ok(false);
+        }
+        let (width, height) = canvas.size;
+        let columns = ((width - 2.0 * MARGIN) / (SIZE * ADVANCE)).max(1.0) as usize;
+        let lines = ((height - 2.0 * MARGIN) / LEADING).max(1.0) as usize;
+        let (mut line, mut column) = (0, 0);
+        while let Some(&byte) = self.data.get(self.pos) {
+            match byte {
+                b'\n' => {
+                    line += 1;
+                    column = 0;
+                }
+                b'\r' => column = 0,
+                b'\t' => column = (column / TAB + 1) * TAB,
+                0x0C => {
+                    self.pos += 1;
+                    break;
+                }
+                // UTF-8 continuation bytes go with the `?` of their lead byte
+                0x80..=0xBF => {}
+                _ => {
+                    if column >= columns {
+                        line += 1;
+                        column = 0;
+                    }
+                    if line >= lines {
+                        break;
+                    }
+                    let c = if byte.is_ascii() { byte as char } else { '?' };
+                    let x = MARGIN + column as f32 * SIZE * ADVANCE;
+                    let y = height - MARGIN - SIZE - line as f32 * LEADING;
+                    canvas.glyph(c, Matrix([SIZE, 0.0, 0.0, SIZE, x, y]), ADVANCE);
+                    column += 1;
+                }
+            }
+            self.pos += 1;
+            if line >= lines {
+                break;
+            }
+        }
+        Ok(true)
+    }
+}
@@ -1,268 +1,248 @@
-use alloc::{collections::VecDeque, vec::Vec, string::String};
-use spin::RwLock;
-use crate::task::{Task, spawn};
-use super::job::{PrintJob, JobStatus, JobPriority};
+//! Print spooler
+//!
+//! Each printer has its own queue, highest priority first. `poll` starts
+//! the next job on every printer that is free and not paused, then moves
+//! each running job on by one step: a page rasterized into the printer's
+//! language, or another window of output handed to the transport its URI
+//! names. Nothing blocks, so a slow printer holds up only its own queue.
+
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, string::String, vec::Vec};
+use spin::{Mutex, RwLock};
+use super::drivers::{escp::ESCPDriver, pcl::PCL5Driver};
+use super::job::{PrintJob, JobStatus};
+use super::protocols::{Destination, Transport};
+use super::queue::PrintQueue;
+use super::raster::{self, Canvas, Source};
+use super::{Language, Printer, PrinterStatus};
+
+// Finished jobs kept for `jobs`
+const HISTORY_LENGTH: usize = 50;
 
 pub struct PrintSpooler {
-    job_queue: RwLock<VecDeque<PrintJob>>,
-    active_jobs: RwLock<Vec<PrintJob>>,
-    completed_jobs: RwLock<Vec<PrintJob>>,
-    paused: RwLock<bool>,
-    max_concurrent_jobs: usize,
+    queues: RwLock<BTreeMap<u32, PrintQueue>>,
+    // The running job of each printer, by printer id
+    active: Mutex<BTreeMap<u32, ActiveJob>>,
+    history: RwLock<VecDeque<PrintJob>>,
+}
+
+struct ActiveJob {
+    job: PrintJob,
+    destination: Destination,
+    stage: Stage,
+}
+
+enum Stage {
+    Rendering(Renderer),
+    Sending(Transport),
+}
+
+struct Renderer {
+    source: Box<dyn Source>,
+    canvas: Canvas,
+    encoder: Encoder,
+    output: Vec<u8>,
+}
+
+enum Encoder {
+    Pcl(PCL5Driver),
+    EscP(ESCPDriver),
 }
 
 impl PrintSpooler {
     pub fn new() -> Self {
         Self {
-            job_queue: RwLock::new(VecDeque::new()),
-            active_jobs: RwLock::new(Vec::new()),
-            completed_jobs: RwLock::new(Vec::new()),
-            paused: RwLock::new(false),
-            max_concurrent_jobs: 4,
+            queues: RwLock::new(BTreeMap::new()),
+            active: Mutex::new(BTreeMap::new()),
+            history: RwLock::new(VecDeque::new()),
         }
     }
 
-    pub fn start(&self) -> Result<(), &'static str> {
-        spawn(Task::new("print_spooler", || {
-            loop {
-                self.process_queue();
-                crate::arch::x86_64::hlt();
-            }
-        }));
-        Ok(())
-    }
-
     pub fn add_job(&self, mut job: PrintJob) -> Result<(), &'static str> {
         job.status = JobStatus::Queued;
         job.queued_time = crate::time::get_timestamp();
-        
-        let mut queue = self.job_queue.write();
-        
-        match job.priority {
-            JobPriority::High => {
-                queue.push_front(job);
-            }
-            JobPriority::Normal => {
-                let pos = queue.iter().position(|j| j.priority == JobPriority::Low)
-                    .unwrap_or(queue.len());
-                queue.insert(pos, job);
-            }
-            JobPriority::Low => {
-                queue.push_back(job);
-            }
-        }
-        
-        Ok(())
-    }
 
-    pub fn cancel_job(&self, job_id: u32) -> Result<(), &'static str> {
-        {
-            let mut queue = self.job_queue.write();
-            if let Some(pos) = queue.iter().position(|j| j.id == job_id) {
-                queue.remove(pos);
-                return Ok(());
-            }
-        }
-        
-        {
-            let mut active = self.active_jobs.write();
-            if let Some(pos) = active.iter().position(|j| j.id == job_id) {
-                active[pos].status = JobStatus::Cancelled;
-                return Ok(());
-            }
-        }
-        
-        Err("Job not found")
+        let mut queues = self.queues.write();
+        queues
+            .entry(job.printer_id)
+            .or_insert_with(|| PrintQueue::new(job.printer_id, String::new()))
+            .add_job(job)
     }
 
-    pub fn pause_job(&self, job_id: u32) -> Result<(), &'static str> {
-        let mut queue = self.job_queue.write();
-        if let Some(job) = queue.iter_mut().find(|j| j.id == job_id) {
-            job.status = JobStatus::Paused;
-            Ok(())
-        } else {
-            Err("Job not found or already processing")
+    pub fn cancel_job(&self, job_id: u32) -> Result<(), &'static str> {
+        let queued = self.queues.read().values().find_map(|queue| {
+            let job = queue.get_jobs().into_iter().find(|j| j.id == job_id)?;
+            queue.remove_job(job_id).ok()?;
+            Some(job)
+        });
+        if let Some(job) = queued {
+            self.finish(job, JobStatus::Cancelled, None);
+            return Ok(());
         }
-    }
 
-    pub fn resume_job(&self, job_id: u32) -> Result<(), &'static str> {
-        let mut queue = self.job_queue.write();
-        if let Some(job) = queue.iter_mut().find(|j| j.id == job_id) {
-            job.status = JobStatus::Queued;
-            Ok(())
-        } else {
-            Err("Job not found")
+        let mut active = self.active.lock();
+        let printer_id = active.iter().find(|(_, a)| a.job.id == job_id).map(|(&id, _)| id);
+        if let Some(running) = printer_id.and_then(|id| active.remove(&id)) {
+            drop(active);
+            // Dropping the transport closes its connection
+            self.finish(running.job, JobStatus::Cancelled, None);
+            return Ok(());
         }
-    }
 
-    pub fn reorder_job(&self, job_id: u32, new_position: usize) -> Result<(), &'static str> {
-        let mut queue = self.job_queue.write();
-        if let Some(pos) = queue.iter().position(|j| j.id == job_id) {
-            if new_position >= queue.len() {
-                return Err("Invalid position");
-            }
-            let job = queue.remove(pos).unwrap();
-            queue.insert(new_position, job);
-            Ok(())
-        } else {
-            Err("Job not found")
-        }
+        Err("Job not found")
     }
 
-    pub fn get_job_status(&self, job_id: u32) -> Option<JobStatus> {
-        if let Some(job) = self.job_queue.read().iter().find(|j| j.id == job_id) {
-            return Some(job.status);
+    /// Every job the spooler knows of: running, then queued, then finished
+    pub fn jobs(&self) -> Vec<PrintJob> {
+        let mut jobs: Vec<PrintJob> = self.active.lock().values().map(|a| a.job.clone()).collect();
+        for queue in self.queues.read().values() {
+            jobs.extend(queue.get_jobs());
         }
-        
-        if let Some(job) = self.active_jobs.read().iter().find(|j| j.id == job_id) {
-            return Some(job.status);
-        }
-        
-        if let Some(job) = self.completed_jobs.read().iter().find(|j| j.id == job_id) {
-            return Some(job.status);
-        }
-        
-        None
-    }
-
-    pub fn get_queue_length(&self) -> usize {
-        self.job_queue.read().len()
+        jobs.extend(self.history.read().iter().cloned());
+        jobs
     }
 
-    pub fn get_active_jobs(&self) -> Vec<PrintJob> {
-        self.active_jobs.read().clone()
+    pub fn get_job(&self, job_id: u32) -> Option<PrintJob> {
+        self.jobs().into_iter().find(|j| j.id == job_id)
     }
 
-    pub fn get_queued_jobs(&self) -> Vec<PrintJob> {
-        self.job_queue.read().iter().cloned().collect()
+    /// Bytes handed to the printer so far and in all, once a job is sending
+    pub fn get_job_progress(&self, job_id: u32) -> Option<(usize, usize)> {
+        self.active.lock().values().find(|a| a.job.id == job_id).and_then(|a| match &a.stage {
+            Stage::Sending(transport) => Some(transport.progress()),
+            Stage::Rendering(_) => None,
+        })
     }
 
-    pub fn get_completed_jobs(&self) -> Vec<PrintJob> {
-        self.completed_jobs.read().clone()
+    /// Whether a printer has a job running
+    pub fn is_busy(&self, printer_id: u32) -> bool {
+        self.active.lock().contains_key(&printer_id)
     }
 
-    pub fn clear_completed_jobs(&self) {
-        self.completed_jobs.write().clear();
-    }
-
-    pub fn pause_spooler(&self) {
-        *self.paused.write() = true;
-    }
-
-    pub fn resume_spooler(&self) {
-        *self.paused.write() = false;
+    /// Drop a removed printer's queue, cancelling what was waiting in it
+    pub fn remove_queue(&self, printer_id: u32) {
+        if let Some(queue) = self.queues.write().remove(&printer_id) {
+            for job in queue.get_jobs() {
+                self.finish(job, JobStatus::Cancelled, None);
+            }
+        }
     }
 
-    pub fn is_paused(&self) -> bool {
-        *self.paused.read()
-    }
+    /// Start jobs on free printers and move every running job on a step
+    pub fn poll(&self, printers: &[Printer]) {
+        let mut active = self.active.lock();
 
-    fn process_queue(&self) {
-        if self.is_paused() {
-            return;
-        }
-        
-        let active_count = self.active_jobs.read().len();
-        if active_count >= self.max_concurrent_jobs {
-            return;
-        }
-        
-        let mut queue = self.job_queue.write();
-        if let Some(mut job) = queue.pop_front() {
-            if job.status == JobStatus::Paused {
-                queue.push_front(job);
-                return;
+        for printer in printers {
+            if printer.status == PrinterStatus::Paused || active.contains_key(&printer.id) {
+                continue;
             }
-            
+            let next = self.queues.read().get(&printer.id).and_then(|queue| queue.get_next_job());
+            let Some(mut job) = next else {
+                continue;
+            };
             job.status = JobStatus::Processing;
             job.start_time = Some(crate::time::get_timestamp());
-            
-            let job_clone = job.clone();
-            self.active_jobs.write().push(job);
-            
-            spawn(Task::new("print_job", move || {
-                self.process_job(job_clone);
-            }));
+            match Self::start(&job, printer) {
+                Ok((destination, stage)) => {
+                    active.insert(printer.id, ActiveJob { job, destination, stage });
+                }
+                Err(e) => self.finish(job, JobStatus::Failed, Some(e)),
+            }
         }
-    }
 
-    fn process_job(&self, mut job: PrintJob) {
-        let printer = match super::get_subsystem().read().as_ref() {
-            Some(subsystem) => subsystem.get_printer(job.printer_id),
-            None => None,
-        };
-        
-        if printer.is_none() {
-            job.status = JobStatus::Failed;
-            job.error_message = Some(String::from("Printer not found"));
-            self.complete_job(job);
-            return;
-        }
-        
-        let result = self.render_job(&job);
-        
-        match result {
-            Ok(data) => {
-                if let Err(e) = self.send_to_printer(&job, data) {
-                    job.status = JobStatus::Failed;
-                    job.error_message = Some(String::from(e));
-                } else {
-                    job.status = JobStatus::Completed;
-                    job.pages_printed = job.total_pages;
-                }
+        let mut done = Vec::new();
+        for (&printer_id, running) in active.iter_mut() {
+            match Self::step(running) {
+                Ok(false) => {}
+                Ok(true) => done.push((printer_id, None)),
+                Err(e) => done.push((printer_id, Some(e))),
             }
-            Err(e) => {
-                job.status = JobStatus::Failed;
-                job.error_message = Some(String::from(e));
+        }
+        for (printer_id, error) in done {
+            if let Some(running) = active.remove(&printer_id) {
+                let status = if error.is_some() { JobStatus::Failed } else { JobStatus::Completed };
+                self.finish(running.job, status, error);
             }
         }
-        
-        job.end_time = Some(crate::time::get_timestamp());
-        self.complete_job(job);
     }
 
-    fn render_job(&self, job: &PrintJob) -> Result<Vec<u8>, &'static str> {
-        let filter_chain = super::filter::FilterChain::new();
-        filter_chain.process(job)
+    fn start(job: &PrintJob, printer: &Printer) -> Result<(Destination, Stage), &'static str> {
+        let destination = Destination::parse(&printer.uri)?;
+        let (width, height) = raster::paper_points(job.options.paper_size);
+        let dpi = printer.language.dpi();
+        let encoder = match printer.language {
+            Language::Raw => {
+                let format = raster::format(&job.data);
+                let copies = job.options.copies.max(1);
+                let transport = Transport::open(&destination, job, format, copies, job.data.to_vec())?;
+                return Ok((destination, Stage::Sending(transport)));
+            }
+            Language::Pcl => Encoder::Pcl(PCL5Driver::new()),
+            Language::EscP => Encoder::EscP(ESCPDriver::new()),
+        };
+        let source = raster::open(job.data.to_vec())?;
+        let canvas = Canvas::new(width, height, (dpi, dpi));
+        let output = match &encoder {
+            Encoder::Pcl(driver) => driver.raster_begin(&job.options),
+            Encoder::EscP(driver) => driver.raster_begin(canvas.bitmap.height),
+        };
+        Ok((destination, Stage::Rendering(Renderer { source, canvas, encoder, output })))
     }
 
-    fn send_to_printer(&self, job: &PrintJob, data: Vec<u8>) -> Result<(), &'static str> {
-        let subsystem = super::get_subsystem().read();
-        if let Some(subsystem) = subsystem.as_ref() {
-            subsystem.drivers.send_to_printer(job.printer_id, data)
-        } else {
-            Err("Print subsystem not initialized")
-        }
-    }
+    // One page drawn, or more output sent; true once the job is done
+    fn step(running: &mut ActiveJob) -> Result<bool, &'static str> {
+        let renderer = match &mut running.stage {
+            Stage::Sending(transport) => return transport.poll(),
+            Stage::Rendering(renderer) => renderer,
+        };
 
-    fn complete_job(&self, job: PrintJob) {
-        let mut active = self.active_jobs.write();
-        active.retain(|j| j.id != job.id);
-        
-        let mut completed = self.completed_jobs.write();
-        completed.push(job);
-        
-        if completed.len() > 100 {
-            completed.drain(0..50);
-        }
+        if running.job.total_pages == 0 {
+            running.job.total_pages = renderer.source.page_count().unwrap_or(0) as u32;
+        }
+        renderer.canvas.clear();
+        if renderer.source.next_page(&mut renderer.canvas)? {
+            let page = &renderer.canvas.bitmap;
+            let encoded = match &mut renderer.encoder {
+                Encoder::Pcl(driver) => driver.raster_page(page, Language::Pcl.dpi()),
+                Encoder::EscP(driver) => driver.raster_page(page),
+            };
+            renderer.output.extend_from_slice(&encoded);
+            running.job.pages_printed += 1;
+            running.job.total_pages = running.job.total_pages.max(running.job.pages_printed);
+            return Ok(false);
+        }
+
+        if running.job.pages_printed == 0 {
+            return Err("document has no pages");
+        }
+        let mut output = core::mem::take(&mut renderer.output);
+        let format = match &renderer.encoder {
+            Encoder::Pcl(driver) => {
+                output.extend_from_slice(&driver.raster_end());
+                "application/vnd.hp-pcl"
+            }
+            Encoder::EscP(driver) => {
+                output.extend_from_slice(&driver.raster_end());
+                // ESC/P has no copy count of its own
+                output = output.repeat(running.job.options.copies.max(1) as usize);
+                "application/octet-stream"
+            }
+        };
+        let transport = Transport::open(&running.destination, &running.job, format, 1, output)?;
+        running.stage = Stage::Sending(transport);
+        Ok(false)
     }
 
-    pub fn get_job_progress(&self, job_id: u32) -> Option<(u32, u32)> {
-        self.active_jobs.read()
-            .iter()
-            .find(|j| j.id == job_id)
-            .map(|j| (j.pages_printed, j.total_pages))
-    }
+    fn finish(&self, mut job: PrintJob, status: JobStatus, error: Option<&'static str>) {
+        job.status = status;
+        job.end_time = Some(crate::time::get_timestamp());
+        job.error_message = error.map(String::from);
+        match error {
+            Some(e) => crate::serial_println!("print: job {} \"{}\" failed: {}", job.id, job.title, e),
+            None => crate::serial_println!("print: job {} \"{}\" {:?}", job.id, job.title, status),
+        }
 
-    pub fn get_estimated_wait_time(&self, job_id: u32) -> Option<u64> {
-        let queue = self.job_queue.read();
-        let position = queue.iter().position(|j| j.id == job_id)?;
-        
-        let estimated_time = queue.iter()
-            .take(position)
-            .map(|j| j.estimated_print_time())
-            .sum();
-        
-        Some(estimated_time)
+        let mut history = self.history.write();
+        history.push_front(job);
+        history.truncate(HISTORY_LENGTH);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,625 @@
+// Process checkpoint and restore
+//
+// A checkpoint captures an executor process whole: registers and FPU state,
+// the pages mapped in its memory, its file descriptors and a pending sleep.
+// Restoring maps the pages back at the same addresses in a new process, so
+// the address range must be free there: the original has exited, was
+// stopped by the checkpoint, or ran on another machine.
+//
+// A checkpoint file is a sequential little-endian stream:
+//
+//   header   "RCKPT001", version, pid, uptime in ms, record count
+//   records  (kind, length, payload): the process, its registers and FPU
+//            state, then one record per region, page, descriptor and timer
+//   trailer  "RCKPTEND" and the CRC-32 of everything before it
+
+use alloc::boxed::Box;
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::context_switch::XSaveArea;
+use super::executor::EXECUTOR;
+use super::pcb::{CpuContext, FileDescriptor, FpuState, MemoryRegion, ProcessControlBlock, WaitReason};
+use crate::debug::kdump::crc32;
+use crate::memory::PageProtection;
+
+pub const SIGNATURE: &[u8; 8] = b"RCKPT001";
+pub const TRAILER_SIGNATURE: &[u8; 8] = b"RCKPTEND";
+pub const VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 32;
+const TRAILER_SIZE: usize = 16;
+const PAGE_SIZE: u64 = 4096;
+
+const RECORD_PROCESS: u32 = 1;
+const RECORD_REGISTERS: u32 = 2;
+const RECORD_FPU: u32 = 3;
+const RECORD_REGION: u32 = 4;
+const RECORD_PAGE: u32 = 5;
+const RECORD_DESCRIPTOR: u32 = 6;
+const RECORD_SLEEP: u32 = 7;
+
+// Layout of the FPU record's state
+const FPU_FXSAVE: u8 = 0;
+const FPU_XSAVE: u8 = 1;
+
+// Page table bits a saved page keeps; PRESENT is implied
+const PAGE_FLAGS: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+// Exit code of a process stopped by its checkpoint (128 + SIGKILL)
+const STOPPED_EXIT_CODE: i32 = 137;
+
+struct SavedPage {
+    address: u64,
+    flags: PageTableFlags,
+    data: Vec<u8>,
+}
+
+/// A process as a checkpoint holds it
+struct Image {
+    pid: u32,
+    uptime_ms: u64,
+    name: String,
+    command_line: String,
+    priority: u8,
+    uid: u32,
+    gid: u32,
+    cpu_time: u64,
+    user_stack: u64,
+    next_fd: i32,
+    context: CpuContext,
+    regions: Vec<MemoryRegion>,
+    pages: Vec<SavedPage>,
+    descriptors: Vec<FileDescriptor>,
+    // Time left of a sleep the process was blocked in
+    sleep_ms: Option<u64>,
+}
+
+/// What a checkpoint wrote
+pub struct Summary {
+    pub name: String,
+    pub pages: usize,
+    pub descriptors: usize,
+    pub size: usize,
+}
+
+const NS_PER_MS: u64 = 1_000_000;
+
+fn uptime_ms() -> u64 {
+    crate::time::clocksource::now_ns() / NS_PER_MS
+}
+
+fn protection_from_raw(raw: u32) -> Option<PageProtection> {
+    [
+        PageProtection::NoAccess,
+        PageProtection::ReadOnly,
+        PageProtection::ReadWrite,
+        PageProtection::WriteCopy,
+        PageProtection::Execute,
+        PageProtection::ExecuteRead,
+        PageProtection::ExecuteReadWrite,
+        PageProtection::ExecuteWriteCopy,
+        PageProtection::Guard,
+        PageProtection::NoCache,
+        PageProtection::WriteCombine,
+    ]
+    .into_iter()
+    .find(|protection| *protection as u32 == raw)
+}
+
+// Address ranges the process's pages may be in: its executor regions, its
+// stack, and the heap and mappings of its user address space
+fn memory_ranges(pcb: &ProcessControlBlock) -> Vec<(u64, u64)> {
+    use crate::memory::userspace::{USER_SPACE_MANAGER, USER_STACK_SIZE};
+
+    let mut ranges: Vec<(u64, u64)> =
+        pcb.address_space.regions.iter().map(|region| (region.start.as_u64(), region.end.as_u64())).collect();
+    if pcb.user_stack.as_u64() != 0 {
+        ranges.push((pcb.user_stack.as_u64().saturating_sub(USER_STACK_SIZE), pcb.user_stack.as_u64()));
+    }
+    if let Some(space) = USER_SPACE_MANAGER.lock().get_address_space(pcb.pid as u64) {
+        ranges.extend(space.regions.values().map(|region| (region.start.as_u64(), region.end.as_u64())));
+        ranges.push((space.heap_start.as_u64(), space.brk.as_u64()));
+    }
+    ranges
+}
+
+// Contents of a user page mapped at `address`
+fn read_page(address: u64) -> Option<SavedPage> {
+    let (phys, flags) = crate::memory::paging::translate_current(VirtAddr::new(address))?;
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return None;
+    }
+    // Physical memory is identity mapped
+    let data = unsafe { core::slice::from_raw_parts(phys.as_u64() as *const u8, PAGE_SIZE as usize) };
+    Some(SavedPage {
+        address,
+        flags: flags & PAGE_FLAGS,
+        data: data.to_vec(),
+    })
+}
+
+fn capture(pcb: &ProcessControlBlock, uptime_ms: u64) -> Result<Image, String> {
+    let sleep_ms = match &pcb.wait_reason {
+        None | Some(WaitReason::None) => None,
+        Some(WaitReason::Sleep(until)) => {
+            Some(until.saturating_sub(crate::time::clocksource::now_ns()).div_ceil(NS_PER_MS))
+        }
+        Some(reason) => return Err(format!("process is blocked on {:?}, which cannot be checkpointed", reason)),
+    };
+
+    // Pages not mapped yet are demand-zero and need no saving
+    let mut pages = BTreeMap::new();
+    for (start, end) in memory_ranges(pcb) {
+        let mut address = start & !(PAGE_SIZE - 1);
+        while address < end {
+            if let Entry::Vacant(entry) = pages.entry(address) {
+                if let Some(page) = read_page(address) {
+                    entry.insert(page);
+                }
+            }
+            address += PAGE_SIZE;
+        }
+    }
+
+    Ok(Image {
+        pid: pcb.pid,
+        uptime_ms,
+        name: pcb.name.clone(),
+        command_line: pcb.command_line.clone(),
+        priority: pcb.priority,
+        uid: pcb.uid,
+        gid: pcb.gid,
+        cpu_time: pcb.cpu_time,
+        user_stack: pcb.user_stack.as_u64(),
+        next_fd: pcb.next_fd,
+        context: pcb.context.clone(),
+        regions: pcb.address_space.regions.clone(),
+        pages: pages.into_values().collect(),
+        descriptors: pcb.file_descriptors.clone(),
+        sleep_ms,
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.u32(string.len() as u32);
+        self.bytes(string.as_bytes());
+    }
+
+    fn record(&mut self, kind: u32, payload: Writer) {
+        self.u32(kind);
+        self.u32(payload.data.len() as u32);
+        self.bytes(&payload.data);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err(String::from("checkpoint is truncated"));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| String::from("checkpoint holds an invalid string"))
+    }
+}
+
+fn encode_registers(context: &CpuContext) -> Writer {
+    let mut out = Writer::default();
+    for value in [
+        context.rax, context.rbx, context.rcx, context.rdx, context.rsi, context.rdi, context.rbp, context.rsp,
+        context.r8, context.r9, context.r10, context.r11, context.r12, context.r13, context.r14, context.r15,
+        context.rip, context.rflags, context.fs_base, context.gs_base,
+    ] {
+        out.u64(value);
+    }
+    for selector in [context.cs, context.ds, context.es, context.fs, context.gs, context.ss] {
+        out.u16(selector);
+    }
+    out
+}
+
+fn decode_registers(input: &mut Reader, context: &mut CpuContext) -> Result<(), String> {
+    for register in [
+        &mut context.rax, &mut context.rbx, &mut context.rcx, &mut context.rdx,
+        &mut context.rsi, &mut context.rdi, &mut context.rbp, &mut context.rsp,
+        &mut context.r8, &mut context.r9, &mut context.r10, &mut context.r11,
+        &mut context.r12, &mut context.r13, &mut context.r14, &mut context.r15,
+        &mut context.rip, &mut context.rflags, &mut context.fs_base, &mut context.gs_base,
+    ] {
+        *register = input.u64()?;
+    }
+    for selector in [
+        &mut context.cs, &mut context.ds, &mut context.es, &mut context.fs, &mut context.gs, &mut context.ss,
+    ] {
+        *selector = input.u16()?;
+    }
+    Ok(())
+}
+
+// Raw bytes of a saved FPU area
+fn state_bytes<T>(state: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(state as *const T as *const u8, size_of::<T>()) }
+}
+
+fn load_state<T>(state: &mut T, bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() != size_of::<T>() {
+        return Err(String::from("FPU state has the wrong size"));
+    }
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), state as *mut T as *mut u8, bytes.len()) };
+    Ok(())
+}
+
+fn encode(image: &Image) -> Vec<u8> {
+    let mut records = Writer::default();
+    let mut count = 0u32;
+    let mut add = |records: &mut Writer, kind: u32, payload: Writer| {
+        records.record(kind, payload);
+        count += 1;
+    };
+
+    let mut process = Writer::default();
+    process.string(&image.name);
+    process.string(&image.command_line);
+    process.u8(image.priority);
+    process.u32(image.uid);
+    process.u32(image.gid);
+    process.u64(image.cpu_time);
+    process.u64(image.user_stack);
+    process.u32(image.next_fd as u32);
+    add(&mut records, RECORD_PROCESS, process);
+
+    add(&mut records, RECORD_REGISTERS, encode_registers(&image.context));
+
+    let mut fpu = Writer::default();
+    if let Some(area) = &image.context.xsave_area {
+        fpu.u8(FPU_XSAVE);
+        fpu.bytes(state_bytes(area.as_ref()));
+    } else if let Some(state) = &image.context.fpu_state {
+        fpu.u8(FPU_FXSAVE);
+        fpu.bytes(state_bytes(state.as_ref()));
+    }
+    if !fpu.data.is_empty() {
+        add(&mut records, RECORD_FPU, fpu);
+    }
+
+    for region in &image.regions {
+        let mut out = Writer::default();
+        out.u64(region.start.as_u64());
+        out.u64(region.end.as_u64());
+        out.u32(region.protection as u32);
+        out.string(&region.name);
+        add(&mut records, RECORD_REGION, out);
+    }
+    for page in &image.pages {
+        let mut out = Writer::default();
+        out.u64(page.address);
+        out.u64(page.flags.bits());
+        out.bytes(&page.data);
+        add(&mut records, RECORD_PAGE, out);
+    }
+    for descriptor in &image.descriptors {
+        let mut out = Writer::default();
+        out.u32(descriptor.fd as u32);
+        out.u32(descriptor.flags);
+        out.u64(descriptor.offset);
+        out.string(&descriptor.path);
+        add(&mut records, RECORD_DESCRIPTOR, out);
+    }
+    if let Some(sleep_ms) = image.sleep_ms {
+        let mut out = Writer::default();
+        out.u64(sleep_ms);
+        add(&mut records, RECORD_SLEEP, out);
+    }
+
+    let mut out = Writer::default();
+    out.bytes(SIGNATURE);
+    out.u32(VERSION);
+    out.u32(image.pid);
+    out.u64(image.uptime_ms);
+    out.u32(count);
+    out.u32(0);
+    out.bytes(&records.data);
+    let crc = crc32(&out.data);
+    out.bytes(TRAILER_SIGNATURE);
+    out.u32(crc);
+    out.u32(0);
+    out.data
+}
+
+fn decode(data: &[u8]) -> Result<Image, String> {
+    if data.len() < HEADER_SIZE + TRAILER_SIZE || &data[..8] != SIGNATURE {
+        return Err(String::from("not a checkpoint"));
+    }
+    let body = &data[..data.len() - TRAILER_SIZE];
+    let mut trailer = Reader { data: &data[body.len()..] };
+    if trailer.take(8)? != TRAILER_SIGNATURE || trailer.u32()? != crc32(body) {
+        return Err(String::from("checkpoint is corrupt"));
+    }
+
+    let mut input = Reader { data: &body[8..] };
+    if input.u32()? != VERSION {
+        return Err(String::from("unsupported checkpoint version"));
+    }
+    let mut image = Image {
+        pid: input.u32()?,
+        uptime_ms: input.u64()?,
+        name: String::new(),
+        command_line: String::new(),
+        priority: 0,
+        uid: 0,
+        gid: 0,
+        cpu_time: 0,
+        user_stack: 0,
+        next_fd: 3,
+        context: CpuContext::new(),
+        regions: Vec::new(),
+        pages: Vec::new(),
+        descriptors: Vec::new(),
+        sleep_ms: None,
+    };
+    let count = input.u32()?;
+    input.u32()?;
+
+    let mut seen_process = false;
+    for _ in 0..count {
+        let kind = input.u32()?;
+        let len = input.u32()? as usize;
+        let mut record = Reader { data: input.take(len)? };
+        match kind {
+            RECORD_PROCESS => {
+                image.name = record.string()?;
+                image.command_line = record.string()?;
+                image.priority = record.u8()?;
+                image.uid = record.u32()?;
+                image.gid = record.u32()?;
+                image.cpu_time = record.u64()?;
+                image.user_stack = record.u64()?;
+                image.next_fd = record.u32()? as i32;
+                seen_process = true;
+            }
+            RECORD_REGISTERS => decode_registers(&mut record, &mut image.context)?,
+            RECORD_FPU => match record.u8()? {
+                FPU_XSAVE => {
+                    let mut area = Box::new(XSaveArea::new());
+                    load_state(area.as_mut(), record.data)?;
+                    image.context.xsave_area = Some(area);
+                }
+                FPU_FXSAVE => {
+                    let mut state = Box::new(FpuState::default());
+                    load_state(state.as_mut(), record.data)?;
+                    image.context.fpu_state = Some(state);
+                }
+                _ => return Err(String::from("unknown FPU state layout")),
+            },
+            RECORD_REGION => image.regions.push(MemoryRegion {
+                start: VirtAddr::try_new(record.u64()?).map_err(|_| String::from("bad region address"))?,
+                end: VirtAddr::try_new(record.u64()?).map_err(|_| String::from("bad region address"))?,
+                protection: protection_from_raw(record.u32()?).ok_or_else(|| String::from("bad region protection"))?,
+                name: record.string()?,
+            }),
+            RECORD_PAGE => {
+                let address = record.u64()?;
+                if address % PAGE_SIZE != 0 || !crate::memory::userspace::is_user_accessible(VirtAddr::new(address)) {
+                    return Err(format!("page at {:#x} is outside user space", address));
+                }
+                let flags = PageTableFlags::from_bits_truncate(record.u64()?) & PAGE_FLAGS;
+                let data = record.take(PAGE_SIZE as usize)?.to_vec();
+                image.pages.push(SavedPage { address, flags, data });
+            }
+            RECORD_DESCRIPTOR => image.descriptors.push(FileDescriptor {
+                fd: record.u32()? as i32,
+                flags: record.u32()?,
+                offset: record.u64()?,
+                path: record.string()?,
+            }),
+            RECORD_SLEEP => image.sleep_ms = Some(record.u64()?),
+            // Records of later versions this one knows nothing about
+            _ => {}
+        }
+    }
+    if !seen_process {
+        return Err(String::from("checkpoint has no process record"));
+    }
+    Ok(image)
+}
+
+fn unmap_pages(addresses: impl Iterator<Item = u64>) {
+    let mapper = unsafe { crate::memory::get_mapper() };
+    for address in addresses {
+        if let Ok((_, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(address))) {
+            flush.flush();
+        }
+    }
+}
+
+// Map the saved pages into fresh frames, all or none
+fn map_pages(pages: &[SavedPage]) -> Result<(), String> {
+    use crate::memory::frame_allocator::{self, FRAME_ALLOCATOR};
+
+    let mapped = |page: &&SavedPage| crate::memory::paging::translate_current(VirtAddr::new(page.address)).is_some();
+    if let Some(page) = pages.iter().find(mapped) {
+        return Err(format!("{:#x} is already mapped; is the process still running?", page.address));
+    }
+
+    let mapper = unsafe { crate::memory::get_mapper() };
+    for (index, page) in pages.iter().enumerate() {
+        let result = frame_allocator::allocate_frame().ok_or("out of memory").and_then(|frame| {
+            // Physical memory is identity mapped
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    page.data.as_ptr(),
+                    frame.start_address().as_u64() as *mut u8,
+                    PAGE_SIZE as usize,
+                );
+            }
+            let flags = page.flags | PageTableFlags::PRESENT;
+            let target = Page::<Size4KiB>::containing_address(VirtAddr::new(page.address));
+            match unsafe { mapper.map_to(target, frame, flags, &mut *FRAME_ALLOCATOR.lock()) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(_) => {
+                    frame_allocator::deallocate_frame(frame);
+                    Err("cannot map page")
+                }
+            }
+        });
+        if let Err(error) = result {
+            unmap_pages(pages[..index].iter().map(|page| page.address));
+            return Err(format!("{} at {:#x}", error, page.address));
+        }
+    }
+    Ok(())
+}
+
+/// Write a checkpoint of executor process `pid` to `path`. With `stop` the
+/// process is terminated once the checkpoint is written and its pages are
+/// unmapped, so it can be restored on this machine.
+pub fn checkpoint(pid: u32, path: &str, stop: bool) -> Result<Summary, String> {
+    let now = uptime_ms();
+
+    // The process stays off the ready queue until the file is written, so
+    // what is saved is what it resumes from
+    let (image, was_ready) = {
+        let mut executor = EXECUTOR.lock();
+        let pcb = executor.get_process(pid).ok_or_else(|| format!("no process {}", pid))?;
+        let image = capture(pcb, now)?;
+        (image, executor.suspend_process(pid))
+    };
+
+    let data = encode(&image);
+    let written = crate::fs::vfs::VFS
+        .lock()
+        .write_file(path, &data)
+        .map_err(|error| format!("cannot write '{}': {:?}", path, error));
+
+    if written.is_ok() && stop {
+        crate::virt::ioctl::release_process(pid);
+        crate::serial::tty::release_process(pid);
+        crate::input::evdev::release_process(pid);
+        crate::container::release_process(pid);
+        crate::fs::file_ops::release_process(pid);
+        crate::memory::mmap::release_process(pid);
+        super::fork::release_process(pid);
+        crate::drivers::block::release_process(pid);
+        EXECUTOR.lock().terminate_process(pid, STOPPED_EXIT_CODE);
+        // The frames stay allocated: the loader and the fault handler take
+        // them from different allocators, and which one owns a page is not
+        // recorded anywhere
+        unmap_pages(image.pages.iter().map(|page| page.address));
+    } else if was_ready {
+        EXECUTOR.lock().resume_process(pid);
+    }
+    written?;
+
+    crate::serial_println!(
+        "[CHECKPOINT] Process {} ({}) saved to {}: {} pages, {} bytes",
+        pid,
+        image.name,
+        path,
+        image.pages.len(),
+        data.len()
+    );
+    Ok(Summary {
+        name: image.name,
+        pages: image.pages.len(),
+        descriptors: image.descriptors.len(),
+        size: data.len(),
+    })
+}
+
+/// Rebuild a process from the checkpoint at `path`, returning its new pid
+pub fn restore(path: &str) -> Result<u32, String> {
+    let data = crate::fs::vfs::VFS
+        .lock()
+        .read_file(path)
+        .map_err(|error| format!("cannot read '{}': {:?}", path, error))?;
+    let image = decode(&data)?;
+    map_pages(&image.pages)?;
+
+    let mut pcb = Box::new(ProcessControlBlock::new(0, image.name, image.command_line));
+    pcb.context = image.context;
+    pcb.user_stack = VirtAddr::new(image.user_stack);
+    pcb.address_space.regions = image.regions;
+    pcb.file_descriptors = image.descriptors;
+    pcb.next_fd = image.next_fd;
+    pcb.priority = image.priority;
+    pcb.uid = image.uid;
+    pcb.gid = image.gid;
+    pcb.cpu_time = image.cpu_time;
+    // The sleep resumes with the time it had left
+    let now = crate::time::clocksource::now_ns();
+    pcb.wait_reason = image.sleep_ms.map(|left| WaitReason::Sleep(now + left * NS_PER_MS));
+
+    let pid = EXECUTOR.lock().add_restored_process(pcb);
+    crate::serial_println!(
+        "[CHECKPOINT] Restored process {} from {} as {} ({} pages)",
+        image.pid,
+        path,
+        pid,
+        image.pages.len()
+    );
+    Ok(pid)
+}
+
@@ -416,7 +416,7 @@ pub unsafe fn yield_to_scheduler(current_context: *mut CpuContext) {
 // Fork-like context copy for creating child processes
 pub fn fork_context(parent: &CpuContext, child: &mut CpuContext) {
     // Copy parent context to child
-    *child = *parent;
+    *child = parent.clone();
     
     // Child gets return value 0 from fork
     child.rax = 0;
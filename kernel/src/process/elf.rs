@@ -9,6 +9,22 @@ const ELF_DATA_LSB: u8 = 1;
 const ELF_VERSION_CURRENT: u8 = 1;
 const ELF_OSABI_NONE: u8 = 0;
 
+// Dynamic section tags
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+// x86_64 relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+const RELA_ENTRY_SIZE: u64 = 24;
+
+// The GNU property note, which says which CET features the code was built for
+const PT_GNU_PROPERTY: u32 = 0x6474_e553;
+const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+const GNU_PROPERTY_X86_FEATURE_1_AND: u32 = 0xc000_0002;
+
 // ELF file types
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u16)]
@@ -99,6 +115,9 @@ pub struct LoadedElf {
     pub segments: Vec<LoadedSegment>,
     pub base_address: VirtAddr,
     pub end_address: VirtAddr,
+    /// GNU_PROPERTY_X86_FEATURE_1_AND, when the image has it: bit 0 for
+    /// IBT, bit 1 for shadow stacks
+    pub x86_features: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -148,12 +167,15 @@ impl ElfLoader {
     pub fn load(data: &[u8]) -> Result<LoadedElf, &'static str> {
         let header = Self::parse_header(data)?;
         
-        // Check if it's an executable
-        if header.elf_type != ElfType::Executable as u16 {
+        // Fixed-address executables and position-independent ones
+        let position_independent = header.elf_type == ElfType::SharedObject as u16;
+        if header.elf_type != ElfType::Executable as u16 && !position_independent {
             return Err("Not an executable ELF file");
         }
         
         let mut segments = Vec::new();
+        let mut dynamic = None;
+        let mut x86_features = None;
         let mut min_vaddr = u64::MAX;
         let mut max_vaddr = 0u64;
         
@@ -169,6 +191,13 @@ impl ElfLoader {
                 *(data.as_ptr().add(ph_offset as usize) as *const Elf64ProgramHeader)
             };
             
+            if ph.segment_type == SegmentType::Dynamic as u32 {
+                dynamic = Some(ph);
+            }
+            if ph.segment_type == PT_GNU_PROPERTY {
+                x86_features = Self::x86_features(data, &ph);
+            }
+            
             // Only load LOAD segments
             if ph.segment_type != SegmentType::Load as u32 {
                 continue;
@@ -201,11 +230,122 @@ impl ElfLoader {
             return Err("No loadable segments found");
         }
         
+        // ASLR picks the load address of position-independent executables
+        let link_base = min_vaddr & !0xFFF;
+        let bias = if position_independent {
+            let base = crate::security::aslr::randomize_image_base(link_base, max_vaddr - link_base, true);
+            base.wrapping_sub(link_base)
+        } else {
+            0
+        };
+        if bias != 0 {
+            if let Some(dynamic) = dynamic {
+                Self::apply_relocations(data, &dynamic, &mut segments, bias)?;
+            }
+            for segment in &mut segments {
+                segment.vaddr = VirtAddr::new(segment.vaddr.as_u64().wrapping_add(bias));
+            }
+        }
+        
         Ok(LoadedElf {
-            entry_point: VirtAddr::new(header.entry),
+            entry_point: VirtAddr::new(header.entry.wrapping_add(bias)),
             segments,
-            base_address: VirtAddr::new(min_vaddr),
-            end_address: VirtAddr::new(max_vaddr),
+            base_address: VirtAddr::new(min_vaddr.wrapping_add(bias)),
+            end_address: VirtAddr::new(max_vaddr.wrapping_add(bias)),
+            x86_features,
+        })
+    }
+    
+    // The x86 feature property in a PT_GNU_PROPERTY note, with the
+    // properties 8-byte aligned as on x86_64
+    fn x86_features(data: &[u8], note: &Elf64ProgramHeader) -> Option<u32> {
+        let note = data.get(note.offset as usize..note.offset.checked_add(note.filesz)? as usize)?;
+        let word = |at: usize| note.get(at..at + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+        let name_size = word(0)? as usize;
+        let desc_size = word(4)? as usize;
+        if word(8)? != NT_GNU_PROPERTY_TYPE_0 || note.get(12..12 + name_size)? != b"GNU\0" {
+            return None;
+        }
+        let desc_start = 12 + ((name_size + 3) & !3);
+        let desc = note.get(desc_start..desc_start + desc_size)?;
+        let mut at = 0;
+        while at + 8 <= desc.len() {
+            let kind = u32::from_le_bytes(desc[at..at + 4].try_into().unwrap());
+            let size = u32::from_le_bytes(desc[at + 4..at + 8].try_into().unwrap()) as usize;
+            if kind == GNU_PROPERTY_X86_FEATURE_1_AND && size == 4 {
+                return desc.get(at + 8..at + 12).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+            }
+            at += 8 + ((size + 7) & !7);
+        }
+        None
+    }
+    
+    // Apply the RELA table named by the dynamic segment to segments linked
+    // at their original addresses. Static PIEs only carry relative
+    // relocations; anything needing symbol lookup is rejected.
+    fn apply_relocations(
+        data: &[u8],
+        dynamic: &Elf64ProgramHeader,
+        segments: &mut [LoadedSegment],
+        bias: u64,
+    ) -> Result<(), &'static str> {
+        let mut rela = 0u64;
+        let mut rela_size = 0u64;
+        let mut rela_entry = RELA_ENTRY_SIZE;
+        
+        let entries = data
+            .get(dynamic.offset as usize..(dynamic.offset + dynamic.filesz) as usize)
+            .ok_or("Invalid dynamic segment")?;
+        for entry in entries.chunks_exact(16) {
+            let tag = i64::from_le_bytes(entry[..8].try_into().unwrap());
+            let value = u64::from_le_bytes(entry[8..].try_into().unwrap());
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = value,
+                DT_RELASZ => rela_size = value,
+                DT_RELAENT => rela_entry = value,
+                _ => {}
+            }
+        }
+        if rela == 0 || rela_size == 0 {
+            return Ok(());
+        }
+        if rela_entry < RELA_ENTRY_SIZE {
+            return Err("Invalid relocation entry size");
+        }
+        
+        let table = Self::segment_data(segments, rela, rela_size as usize)
+            .ok_or("Relocation table outside loaded segments")?
+            .to_vec();
+        for entry in table.chunks_exact(rela_entry as usize) {
+            let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            let info = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            let addend = i64::from_le_bytes(entry[16..24].try_into().unwrap());
+            match info as u32 {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let target = Self::segment_data_mut(segments, offset, 8)
+                        .ok_or("Relocation target outside loaded segments")?;
+                    target.copy_from_slice(&bias.wrapping_add(addend as u64).to_le_bytes());
+                }
+                _ => return Err("Unsupported relocation type"),
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn segment_data(segments: &[LoadedSegment], vaddr: u64, len: usize) -> Option<&[u8]> {
+        segments.iter().find_map(|segment| {
+            let start = vaddr.checked_sub(segment.vaddr.as_u64())? as usize;
+            segment.data.get(start..start + len)
+        })
+    }
+    
+    fn segment_data_mut(segments: &mut [LoadedSegment], vaddr: u64, len: usize) -> Option<&mut [u8]> {
+        segments.iter_mut().find_map(|segment| {
+            let start = vaddr.checked_sub(segment.vaddr.as_u64())? as usize;
+            segment.data.get_mut(start..start + len)
         })
     }
     
@@ -5,13 +5,19 @@ use super::context_switch::{init_context, switch_context};
 use super::elf::ElfLoader;
 use super::pe_loader::PeLoader;
 use alloc::{vec::Vec, string::{String, ToString}, boxed::Box, collections::BTreeMap};
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use x86_64::{VirtAddr, structures::paging::PageTableFlags};
 use crate::memory::paging;
 use crate::interrupts::TIMER_TICKS;
+use crate::security::mitigations::{self, CetState};
 use crate::serial_println;
 
+// Delay before retrying a wakeup that found the executor locked
+const SLEEP_RETRY_NS: u64 = 100_000;
+// Exit codes kept for collection; past this, those of the lowest pids go
+const MAX_EXIT_CODES: usize = 64;
+
 lazy_static! {
     pub static ref EXECUTOR: Mutex<ProcessExecutor> = Mutex::new(ProcessExecutor::new());
 }
@@ -19,11 +25,14 @@ lazy_static! {
 pub struct ProcessExecutor {
     processes: BTreeMap<u32, Box<ProcessControlBlock>>,
     current_pid: Option<u32>,
-    next_pid: u32,
     ready_queue: Vec<u32>,
     blocked_queue: Vec<u32>,
     time_quantum: u32,
     current_quantum: u32,
+    // Quantum of the running process, scaled by its resource group's CPU shares
+    current_slice: u32,
+    // Exit codes of processes that ended, until collected
+    exit_codes: BTreeMap<u32, i32>,
 }
 
 impl ProcessExecutor {
@@ -31,11 +40,12 @@ impl ProcessExecutor {
         Self {
             processes: BTreeMap::new(),
             current_pid: None,
-            next_pid: 1,
             ready_queue: Vec::new(),
             blocked_queue: Vec::new(),
             time_quantum: 10,  // 10 timer ticks per process
             current_quantum: 0,
+            current_slice: 10,
+            exit_codes: BTreeMap::new(),
         }
     }
     
@@ -88,26 +98,8 @@ impl ProcessExecutor {
     }
     
     pub fn create_process(&mut self, name: String, binary_data: &[u8]) -> Result<u32, &'static str> {
-        // Detect format and load executable
-        let (entry_point, is_pe) = if PeLoader::validate_pe(binary_data) {
-            // Load PE/COFF executable
-            let loaded_pe = PeLoader::load_pe(binary_data)?;
-            
-            // Check if it's a DLL
-            if loaded_pe.is_dll {
-                return Err("Cannot execute DLL as process");
-            }
-            
-            (loaded_pe.entry_point, true)
-        } else {
-            // Try loading as ELF
-            let loaded_elf = ElfLoader::load(binary_data)?;
-            (loaded_elf.entry_point, false)
-        };
-        
         // Allocate PID
-        let pid = self.next_pid;
-        self.next_pid += 1;
+        let pid = self.allocate_pid();
         
         // Create PCB
         let mut pcb = Box::new(ProcessControlBlock::new(
@@ -115,52 +107,11 @@ impl ProcessExecutor {
             name.clone(),
             name.clone(),
         ));
-        
-        // Allocate stacks
-        let kernel_stack = allocate_kernel_stack();
-        let user_stack = allocate_user_stack();
-        
-        pcb.kernel_stack = VirtAddr::new(kernel_stack);
-        pcb.user_stack = VirtAddr::new(user_stack);
-        
-        // Initialize context for user process
-        init_context(
-            &mut pcb.context,
-            entry_point.as_u64(),
-            user_stack,
-            false,  // User process
-        );
-        
-        // Map segments into process address space
-        if is_pe {
-            let loaded_pe = PeLoader::load_pe(binary_data)?;
-            for section in &loaded_pe.sections {
-                let protection = if section.characteristics & 0x20000000 != 0 {
-                    crate::memory::PageProtection::ExecuteReadWrite
-                } else if section.characteristics & 0x80000000 != 0 {
-                    crate::memory::PageProtection::ReadWrite
-                } else {
-                    crate::memory::PageProtection::ReadOnly
-                };
-                
-                pcb.address_space.add_region(crate::process::pcb::MemoryRegion {
-                    start: VirtAddr::new(section.virtual_address.as_u64()),
-                    end: VirtAddr::new(section.virtual_address.as_u64() + section.virtual_size as u64),
-                    protection,
-                    name: section.name.clone(),
-                });
-            }
-        } else {
-            let loaded_elf = ElfLoader::load(binary_data)?;
-            for segment in &loaded_elf.segments {
-                pcb.address_space.add_region(crate::process::pcb::MemoryRegion {
-                    start: segment.vaddr,
-                    end: VirtAddr::new(segment.vaddr.as_u64() + segment.size as u64),
-                    protection: crate::memory::PageProtection::ExecuteReadWrite,
-                    name: String::from("code"),
-                });
-            }
+        if let Err(error) = load_image(&mut pcb, binary_data) {
+            crate::memory::mmap::release_process(pid);
+            return Err(error);
         }
+        pcb.kernel_stack = VirtAddr::new(allocate_kernel_stack());
         
         // Add to process table and ready queue
         self.processes.insert(pid, pcb);
@@ -168,7 +119,7 @@ impl ProcessExecutor {
         
         // Update process manager
         let mut pm = PROCESS_MANAGER.lock();
-        pm.create_process(name, self.current_pid.map(|pid| ProcessId(pid)));
+        pm.add_process(ProcessId(pid), name, self.current_pid.map(|pid| ProcessId(pid)));
         
         serial_println!("Created process with PID {}", pid);
         Ok(pid)
@@ -177,13 +128,30 @@ impl ProcessExecutor {
     pub fn terminate_process(&mut self, pid: u32, exit_code: i32) {
         if let Some(mut pcb) = self.processes.remove(&pid) {
             pcb.exit_code = Some(exit_code);
+            if self.exit_codes.len() >= MAX_EXIT_CODES {
+                self.exit_codes.pop_first();
+            }
+            self.exit_codes.insert(pid, exit_code);
             
             // Remove from queues
             self.ready_queue.retain(|&p| p != pid);
             self.blocked_queue.retain(|&p| p != pid);
             
+            // Whoever is waiting on it can collect the exit code now
+            let waiting: Vec<u32> = self
+                .processes
+                .iter()
+                .filter(|(_, pcb)| matches!(pcb.wait_reason, Some(super::pcb::WaitReason::WaitPid(target)) if target == pid))
+                .map(|(&waiter, _)| waiter)
+                .collect();
+            for waiter in waiting {
+                self.unblock_process(waiter);
+            }
+            
             // Free resources (stacks, memory regions, etc.)
-            // This would deallocate memory
+            // This would deallocate memory; the user address space goes
+            // here for processes ended without releasing it
+            crate::memory::userspace::USER_SPACE_MANAGER.lock().destroy_address_space(pid as u64);
             
             serial_println!("Process {} terminated with exit code {}", pid, exit_code);
             
@@ -199,7 +167,9 @@ impl ProcessExecutor {
         // Save current process context if needed
         if let Some(current) = self.current_pid {
             if let Some(pcb) = self.processes.get_mut(&current) {
-                // Context would be saved by interrupt handler
+                // Context would be saved by interrupt handler, but for
+                // the CET registers
+                mitigations::save_user_cet(&mut pcb.cet);
                 // Move to back of ready queue if still ready
                 if !self.blocked_queue.contains(&current) {
                     self.ready_queue.retain(|&p| p != current);
@@ -210,11 +180,30 @@ impl ProcessExecutor {
         
         // Pick next process from ready queue
         if let Some(&next_pid) = self.ready_queue.first() {
+            // A thread runs in its process's memory, and its system calls
+            // act for the process
+            let process = self.processes.get(&next_pid).and_then(|pcb| pcb.thread_of).unwrap_or(next_pid);
+            // Forked processes share addresses, so the page table has to
+            // show the one about to run
+            if !crate::memory::demand_paging::switch_to(process) {
+                self.current_pid = Some(0);
+                return;
+            }
+            // Syscalls find their caller through the process manager
+            match PROCESS_MANAGER.try_lock() {
+                Some(mut pm) => pm.current_process = Some(ProcessId(process)),
+                None => {
+                    self.current_pid = Some(0);
+                    return;
+                }
+            }
             self.current_pid = Some(next_pid);
             self.current_quantum = 0;
+            self.current_slice = crate::container::group::time_slice(next_pid, self.time_quantum);
             
             // Switch to next process
             if let Some(next_pcb) = self.processes.get(&next_pid) {
+                mitigations::load_user_cet(&next_pcb.cet);
                 // This would perform actual context switch
                 serial_println!("Scheduling process {} ({})", next_pid, next_pcb.name);
             }
@@ -232,10 +221,11 @@ impl ProcessExecutor {
             if let Some(pcb) = self.processes.get_mut(&pid) {
                 pcb.cpu_time += 1;
             }
+            crate::container::group::charge_cpu(pid, 1);
         }
         
         // Check if time quantum expired
-        if self.current_quantum >= self.time_quantum {
+        if self.current_quantum >= self.current_slice {
             self.schedule_next();
         }
     }
@@ -269,10 +259,118 @@ impl ProcessExecutor {
         }
     }
     
+    /// Block a process until the clocksource reaches `deadline_ns`; an
+    /// hrtimer puts it back on the ready queue
+    pub fn sleep_until(&mut self, pid: u32, deadline_ns: u64) {
+        self.block_process(pid, super::pcb::WaitReason::Sleep(deadline_ns));
+        crate::time::hrtimer::start(deadline_ns, wake_sleeper, pid as u64);
+    }
+    
+    /// Take a process off the ready queue without blocking it; true if it
+    /// was ready
+    pub fn suspend_process(&mut self, pid: u32) -> bool {
+        let was_ready = self.ready_queue.contains(&pid);
+        self.ready_queue.retain(|&p| p != pid);
+        if self.current_pid == Some(pid) {
+            self.current_pid = None;
+            self.schedule_next();
+        }
+        was_ready
+    }
+    
+    pub fn resume_process(&mut self, pid: u32) {
+        if self.processes.contains_key(&pid) && !self.ready_queue.contains(&pid) {
+            self.ready_queue.push(pid);
+        }
+    }
+    
+    /// A pid for a new process, from the process manager
+    pub fn allocate_pid(&mut self) -> u32 {
+        PROCESS_MANAGER.lock().allocate_id().0
+    }
+    
+    /// Add a process forked or spawned by `parent`, under the pid it was
+    /// given by `allocate_pid`
+    pub fn add_child_process(&mut self, mut pcb: Box<ProcessControlBlock>, parent: Option<u32>) -> u32 {
+        let pid = pcb.pid;
+        pcb.ppid = parent;
+        pcb.kernel_stack = VirtAddr::new(allocate_kernel_stack());
+        let name = pcb.name.clone();
+        self.processes.insert(pid, pcb);
+        self.ready_queue.push(pid);
+        
+        let mut pm = PROCESS_MANAGER.lock();
+        pm.add_process(ProcessId(pid), name, parent.map(ProcessId));
+        pid
+    }
+    
+    /// Add a thread made by `fork::thread`, under the id it was given by
+    /// `allocate_pid`. Threads are not processes to the process manager.
+    pub fn add_thread(&mut self, mut pcb: Box<ProcessControlBlock>) -> u32 {
+        let tid = pcb.pid;
+        pcb.kernel_stack = VirtAddr::new(allocate_kernel_stack());
+        self.processes.insert(tid, pcb);
+        self.ready_queue.push(tid);
+        tid
+    }
+    
+    /// Add a process rebuilt from a checkpoint, under a new pid
+    pub fn add_restored_process(&mut self, mut pcb: Box<ProcessControlBlock>) -> u32 {
+        let pid = self.allocate_pid();
+        
+        pcb.pid = pid;
+        pcb.ppid = self.current_pid;
+        pcb.kernel_stack = VirtAddr::new(allocate_kernel_stack());
+        let name = pcb.name.clone();
+        if let Some(super::pcb::WaitReason::Sleep(deadline)) = pcb.wait_reason {
+            crate::time::hrtimer::start(deadline, wake_sleeper, pid as u64);
+        }
+        if pcb.wait_reason.is_some() {
+            self.blocked_queue.push(pid);
+        } else {
+            self.ready_queue.push(pid);
+        }
+        self.processes.insert(pid, pcb);
+        
+        let mut pm = PROCESS_MANAGER.lock();
+        pm.add_process(ProcessId(pid), name, self.current_pid.map(|pid| ProcessId(pid)));
+        
+        serial_println!("Restored process with PID {}", pid);
+        pid
+    }
+    
+    pub fn get_process(&self, pid: u32) -> Option<&ProcessControlBlock> {
+        self.processes.get(&pid).map(|b| b.as_ref())
+    }
+    
+    pub fn get_process_mut(&mut self, pid: u32) -> Option<&mut ProcessControlBlock> {
+        self.processes.get_mut(&pid).map(|b| b.as_mut())
+    }
+    
     pub fn get_current_process(&self) -> Option<&ProcessControlBlock> {
         self.current_pid.and_then(|pid| self.processes.get(&pid).map(|b| b.as_ref()))
     }
     
+    pub fn processes(&self) -> impl Iterator<Item = &ProcessControlBlock> {
+        self.processes.values().map(|b| b.as_ref())
+    }
+    
+    pub fn set_oom_score_adj(&mut self, pid: u32, adj: i16) -> bool {
+        match self.processes.get_mut(&pid) {
+            Some(pcb) => {
+                pcb.oom_score_adj = adj;
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// The exit code of a process that has ended, once; None if it is still
+    /// running, or ended too long ago
+    pub fn take_exit_code(&mut self, pid: u32) -> Option<i32> {
+        self.exit_codes.remove(&pid)
+    }
+    
     pub fn get_current_pid(&self) -> Option<u32> {
         self.current_pid
     }
@@ -296,6 +394,94 @@ impl ProcessExecutor {
     }
 }
 
+/// Load an executable into `pcb`: its entry point, a user stack and a
+/// region for each section or segment. The PCB is left alone on failure.
+pub fn load_image(pcb: &mut ProcessControlBlock, binary_data: &[u8]) -> Result<(), &'static str> {
+    // Detect format and load executable. Loading picks the image base, so
+    // the image is loaded exactly once.
+    let (entry_point, loaded_pe, loaded_elf) = if PeLoader::validate_pe(binary_data) {
+        // Load PE/COFF executable
+        let loaded_pe = PeLoader::load_pe(binary_data)?;
+        
+        // Check if it's a DLL
+        if loaded_pe.is_dll {
+            return Err("Cannot execute DLL as process");
+        }
+        
+        (loaded_pe.entry_point, Some(loaded_pe), None)
+    } else {
+        // Try loading as ELF
+        let loaded_elf = ElfLoader::load(binary_data)?;
+        (loaded_elf.entry_point, None, Some(loaded_elf))
+    };
+    
+    // The user stack is the one in the layout the process's address space
+    // was given
+    let user_stack = crate::memory::userspace::USER_SPACE_MANAGER
+        .lock()
+        .get_address_space(pcb.pid as u64)
+        .map(|space| space.stack_top.as_u64())
+        .ok_or("Process has no address space")?;
+    
+    // Initialize context for user process
+    init_context(
+        &mut pcb.context,
+        entry_point.as_u64(),
+        user_stack,
+        false,  // User process
+    );
+    
+    pcb.user_stack = VirtAddr::new(user_stack);
+    pcb.address_space.stack_start = VirtAddr::new(user_stack);
+    pcb.address_space.stack_end = VirtAddr::new(user_stack - crate::memory::userspace::USER_STACK_SIZE);
+    
+    // Map segments into process address space
+    pcb.address_space.regions.clear();
+    if let Some(loaded_pe) = &loaded_pe {
+        for section in &loaded_pe.sections {
+            let protection = if section.characteristics & 0x20000000 != 0 {
+                crate::memory::PageProtection::ExecuteReadWrite
+            } else if section.characteristics & 0x80000000 != 0 {
+                crate::memory::PageProtection::ReadWrite
+            } else {
+                crate::memory::PageProtection::ReadOnly
+            };
+            
+            pcb.address_space.add_region(crate::process::pcb::MemoryRegion {
+                start: VirtAddr::new(section.virtual_address.as_u64()),
+                end: VirtAddr::new(section.virtual_address.as_u64() + section.virtual_size as u64),
+                protection,
+                name: section.name.clone(),
+            });
+        }
+    } else if let Some(loaded_elf) = &loaded_elf {
+        for segment in &loaded_elf.segments {
+            pcb.address_space.add_region(crate::process::pcb::MemoryRegion {
+                start: segment.vaddr,
+                end: VirtAddr::new(segment.vaddr.as_u64() + segment.size as u64),
+                protection: crate::memory::PageProtection::ExecuteReadWrite,
+                name: String::from("code"),
+            });
+        }
+    }
+    
+    // The shadow stack sits below the user stack, a guard page between
+    let stack_bottom = user_stack - USER_STACK_SIZE as u64 - 0x1000;
+    let shadow_stack = VirtAddr::new(stack_bottom - USER_STACK_SIZE as u64)..VirtAddr::new(stack_bottom);
+    pcb.cet = CetState::for_image(loaded_elf.as_ref().and_then(|elf| elf.x86_features), shadow_stack);
+    if let Some(shadow_stack) = pcb.cet.shadow_stack.clone() {
+        crate::memory::demand_paging::map_shadow_stack(shadow_stack.clone());
+        pcb.address_space.add_region(crate::process::pcb::MemoryRegion {
+            start: shadow_stack.start,
+            end: shadow_stack.end,
+            protection: crate::memory::PageProtection::ReadOnly,
+            name: String::from("shadow stack"),
+        });
+    }
+    
+    Ok(())
+}
+
 // Entry point for idle process
 extern "C" fn idle_process_entry() -> ! {
     loop {
@@ -317,6 +503,24 @@ extern "C" fn init_process_entry() -> ! {
 }
 
 // Allocate a kernel stack
+// hrtimer callback ending a sleep. A timer left over from an earlier sleep
+// the process was woken from early must not cut a later one short.
+fn wake_sleeper(pid: u64) {
+    let Some(mut executor) = EXECUTOR.try_lock() else {
+        // Interrupted the executor's holder; come back shortly
+        crate::time::hrtimer::start_after(SLEEP_RETRY_NS, wake_sleeper, pid);
+        return;
+    };
+    let pid = pid as u32;
+    let due = match executor.get_process(pid).and_then(|pcb| pcb.wait_reason.as_ref()) {
+        Some(super::pcb::WaitReason::Sleep(deadline)) => *deadline <= crate::time::clocksource::now_ns(),
+        _ => false,
+    };
+    if due {
+        executor.unblock_process(pid);
+    }
+}
+
 fn allocate_kernel_stack() -> u64 {
     // This would allocate actual memory
     // For now, return a dummy address
@@ -328,18 +532,6 @@ fn allocate_kernel_stack() -> u64 {
     }
 }
 
-// Allocate a user stack
-fn allocate_user_stack() -> u64 {
-    // This would allocate actual memory in user space
-    // For now, return a dummy address
-    static mut NEXT_STACK: u64 = 0x7FFF_FF00_0000;
-    unsafe {
-        let stack = NEXT_STACK;
-        NEXT_STACK -= USER_STACK_SIZE as u64;
-        stack
-    }
-}
-
 // System call handler for process operations
 pub fn handle_process_syscall(syscall: u64, arg1: u64, arg2: u64, arg3: u64) -> i64 {
     match syscall {
@@ -0,0 +1,311 @@
+// Process creation, Unix style
+//
+// fork() copies the caller: its registers, its descriptors, which then
+// share their open files and positions with the parent's, and its memory,
+// copy-on-write, so that parent and child share each page until one of them
+// writes to it. vfork() skips the copy: the child runs in its parent's
+// memory while the parent waits for it to exec or exit. spawn() starts a
+// new program directly, as posix_spawn() and CreateProcess() do, handing
+// down the descriptors its parent keeps across exec. exec() replaces the
+// caller's program, closing its close-on-exec descriptors. thread() starts
+// another thread in the caller's process, sharing everything but its
+// registers and stacks.
+//
+// /dev/kvm and serial port handles are not inherited: a VM belongs to the
+// process that created it, and a port is lent to one process at a time.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use x86_64::VirtAddr;
+
+use super::context_switch::{fork_context, init_context};
+use super::executor::{load_image, EXECUTOR};
+use super::pcb::{ProcessControlBlock, WaitReason};
+use crate::drivers::block;
+use crate::fs::{file_ops, FileSystemError};
+use crate::memory::demand_paging;
+use crate::memory::mmap::{self, Backing};
+use crate::memory::userspace::USER_STACK_SIZE;
+use crate::security::mitigations::{self, CetFeatures};
+use crate::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    NoSuchProcess,
+    NoMemory,
+    /// The program could not be found
+    NotFound,
+    AccessDenied,
+    /// Not an executable this kernel can load
+    BadImage,
+}
+
+// Parents waiting on their vfork children, by child
+static VFORKED: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+// The process each thread runs in, by thread, until the thread is joined
+// or the process exits
+static THREADS: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+
+// A copy of `parent` to run as a child, without memory of its own yet
+fn duplicate(parent: &ProcessControlBlock, pid: u32) -> Box<ProcessControlBlock> {
+    let mut child = Box::new(ProcessControlBlock::new(pid, parent.name.clone(), parent.command_line.clone()));
+    fork_context(&parent.context, &mut child.context);
+    child.user_stack = parent.user_stack;
+    child.address_space.regions = parent.address_space.regions.clone();
+    child.address_space.heap_start = parent.address_space.heap_start;
+    child.address_space.heap_end = parent.address_space.heap_end;
+    child.address_space.stack_start = parent.address_space.stack_start;
+    child.address_space.stack_end = parent.address_space.stack_end;
+    child.oom_score_adj = parent.oom_score_adj;
+    child.file_descriptors = parent.file_descriptors.clone();
+    child.next_fd = parent.next_fd;
+    child.priority = parent.priority;
+    child.uid = parent.uid;
+    child.gid = parent.gid;
+    // The parent is the process running, so its shadow stack pointer is in
+    // the registers rather than its PCB
+    child.cet = parent.cet.clone();
+    mitigations::save_user_cet(&mut child.cet);
+    child
+}
+
+/// Copy process `parent`. Returns the child's pid; the child sees fork
+/// return 0.
+pub fn fork(parent: u32) -> Result<u32, SpawnError> {
+    let (child, ranges) = {
+        let mut executor = EXECUTOR.lock();
+        let pcb = executor.get_process(parent).ok_or(SpawnError::NoSuchProcess)?;
+        let ranges = pcb.address_space.ranges();
+        let pid = executor.allocate_pid();
+        let child = duplicate(executor.get_process(parent).ok_or(SpawnError::NoSuchProcess)?, pid);
+        (child, ranges)
+    };
+
+    // The memory is copied before the child can run
+    mmap::fork(parent, child.pid, &ranges).map_err(|_| SpawnError::NoMemory)?;
+    file_ops::fork_process(parent, child.pid);
+    block::fork_process(parent, child.pid);
+
+    let pid = EXECUTOR.lock().add_child_process(child, Some(parent));
+    crate::serial_println!("Process {} forked {}", parent, pid);
+    Ok(pid)
+}
+
+/// Start a child of `parent` in `parent`'s own memory, and block `parent`
+/// until the child execs or exits. Returns the child's pid.
+pub fn vfork(parent: u32) -> Result<u32, SpawnError> {
+    let mut executor = EXECUTOR.lock();
+    let pid = executor.allocate_pid();
+    let child = duplicate(executor.get_process(parent).ok_or(SpawnError::NoSuchProcess)?, pid);
+    file_ops::fork_process(parent, pid);
+    block::fork_process(parent, pid);
+    VFORKED.lock().insert(pid, parent);
+
+    executor.add_child_process(child, Some(parent));
+    executor.block_process(parent, WaitReason::Vfork(pid));
+    Ok(pid)
+}
+
+/// Start a thread in the process `caller` runs in, at `entry` with `arg`
+/// as its first argument and `stack` as its stack pointer. Returns the
+/// thread's id, from the same numbers as pids.
+pub fn thread(caller: u32, entry: VirtAddr, stack: VirtAddr, arg: u64) -> Result<u32, SpawnError> {
+    let (process, shadow_stacks, mut thread) = {
+        let executor = EXECUTOR.lock();
+        let parent = executor.get_process(caller).ok_or(SpawnError::NoSuchProcess)?;
+        let process = parent.thread_of.unwrap_or(caller);
+        let mut thread = Box::new(ProcessControlBlock::new(0, parent.name.clone(), parent.command_line.clone()));
+        thread.thread_of = Some(process);
+        thread.priority = parent.priority;
+        thread.uid = parent.uid;
+        thread.gid = parent.gid;
+        thread.cet = parent.cet.clone();
+        (process, parent.cet.features.contains(CetFeatures::SHADOW_STACK), thread)
+    };
+    init_context(&mut thread.context, entry.as_u64(), stack.as_u64(), false);
+    thread.context.rdi = arg;
+    thread.user_stack = stack;
+
+    // A shadow stack of its own, placed as mmap() would place it
+    let shadow_stack = if shadow_stacks {
+        let flags = mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS;
+        let start = mmap::map(process, VirtAddr::zero(), USER_STACK_SIZE, mmap::PROT_NONE, flags, Backing::Anonymous)
+            .map_err(|_| SpawnError::NoMemory)?;
+        demand_paging::map_shadow_stack(start..start + USER_STACK_SIZE);
+        Some(start..start + USER_STACK_SIZE)
+    } else {
+        None
+    };
+    thread.cet = thread.cet.for_thread(shadow_stack);
+
+    let mut executor = EXECUTOR.lock();
+    thread.pid = executor.allocate_pid();
+    let tid = executor.add_thread(thread);
+    THREADS.lock().insert(tid, process);
+    crate::serial_println!("Process {} started thread {}", process, tid);
+    Ok(tid)
+}
+
+/// End thread `tid` with `status`, for its process to join. False if `tid`
+/// is not a thread started by `thread`: a process's first thread ends only
+/// with the process.
+pub fn exit_thread(tid: u32, status: i32) -> bool {
+    let (process, shadow_stack) = {
+        let executor = EXECUTOR.lock();
+        let Some(pcb) = executor.get_process(tid) else {
+            return false;
+        };
+        let Some(process) = pcb.thread_of else {
+            return false;
+        };
+        (process, pcb.cet.shadow_stack.clone())
+    };
+    if let Some(shadow_stack) = shadow_stack {
+        let _ = mmap::unmap(process, shadow_stack.start, shadow_stack.end - shadow_stack.start);
+    }
+    EXECUTOR.lock().terminate_process(tid, status);
+    true
+}
+
+/// The status of thread `tid` of the process `caller` runs in, once it has
+/// exited. Until then `caller` is blocked, for the thread's exit to wake,
+/// and None is returned.
+pub fn join(caller: u32, tid: u32) -> Result<Option<i32>, SpawnError> {
+    let mut executor = EXECUTOR.lock();
+    let process = executor
+        .get_process(caller)
+        .map(|pcb| pcb.thread_of.unwrap_or(caller))
+        .ok_or(SpawnError::NoSuchProcess)?;
+    let mut threads = THREADS.lock();
+    if threads.get(&tid) != Some(&process) {
+        return Err(SpawnError::NoSuchProcess);
+    }
+    if let Some(status) = executor.take_exit_code(tid) {
+        threads.remove(&tid);
+        return Ok(Some(status));
+    }
+    if executor.get_process(tid).is_none() {
+        // Ended so long ago that its status is gone
+        threads.remove(&tid);
+        return Err(SpawnError::NoSuchProcess);
+    }
+    executor.block_process(caller, WaitReason::WaitPid(tid));
+    Ok(None)
+}
+
+// Read an executable, with the caller's access
+fn read_program(path: &str) -> Result<Vec<u8>, SpawnError> {
+    crate::fs::vfs::VFS.lock().read_file(path).map_err(|error| match error {
+        FileSystemError::PermissionDenied => SpawnError::AccessDenied,
+        _ => SpawnError::NotFound,
+    })
+}
+
+fn program_name(path: &str) -> String {
+    String::from(path.rsplit(['/', '\\']).next().unwrap_or(path))
+}
+
+/// Start the program at `path` as a child of `parent`, or of no one for
+/// the kernel. With `inherit`, the child gets the descriptors `parent`
+/// keeps open across exec. Returns the child's pid.
+pub fn spawn(parent: Option<u32>, path: &str, command_line: String, inherit: bool) -> Result<u32, SpawnError> {
+    let binary = read_program(path)?;
+    // The image goes into the layout of the child's address space, so the
+    // pid comes first
+    let pid = EXECUTOR.lock().allocate_pid();
+    let mut child = Box::new(ProcessControlBlock::new(pid, program_name(path), command_line));
+    if load_image(&mut child, &binary).is_err() {
+        mmap::release_process(pid);
+        return Err(SpawnError::BadImage);
+    }
+
+    {
+        let executor = EXECUTOR.lock();
+        if let Some(pcb) = parent.and_then(|parent| executor.get_process(parent)) {
+            child.priority = pcb.priority;
+            child.uid = pcb.uid;
+            child.gid = pcb.gid;
+        }
+    }
+    if let (Some(parent), true) = (parent, inherit) {
+        file_ops::spawn_process(parent, child.pid);
+    }
+    if let Some(parent) = parent {
+        block::fork_process(parent, child.pid);
+    }
+
+    let pid = EXECUTOR.lock().add_child_process(child, parent);
+    crate::serial_println!("Spawned {} as process {}", path, pid);
+    Ok(pid)
+}
+
+/// Replace process `pid`'s program with the one at `path`. On failure the
+/// process carries on with the one it has.
+pub fn exec(pid: u32, path: &str, command_line: String) -> Result<(), SpawnError> {
+    let binary = read_program(path)?;
+    let mut image = ProcessControlBlock::new(pid, program_name(path), command_line);
+    load_image(&mut image, &binary).map_err(|_| SpawnError::BadImage)?;
+
+    let old = EXECUTOR
+        .lock()
+        .get_process(pid)
+        .map(|pcb| pcb.address_space.ranges())
+        .ok_or(SpawnError::NoSuchProcess)?;
+    // A vfork child gives its parent back the memory it borrowed; anyone
+    // else's old image goes
+    if !release_vfork(pid) {
+        mmap::release_image(pid, &old);
+    }
+    file_ops::exec_process(pid);
+
+    let mut executor = EXECUTOR.lock();
+    let pcb = executor.get_process_mut(pid).ok_or(SpawnError::NoSuchProcess)?;
+    pcb.name = image.name;
+    pcb.command_line = image.command_line;
+    pcb.context = image.context;
+    pcb.user_stack = image.user_stack;
+    pcb.address_space = image.address_space;
+    // The caller is the process exec'ing, which goes on with the new
+    // image's CET state
+    pcb.cet = image.cet;
+    mitigations::load_user_cet(&pcb.cet);
+    crate::serial_println!("Process {} exec'd {}", pid, path);
+    Ok(())
+}
+
+// Wake the parent a vfork child borrowed memory from; false if `child` was
+// not vforked
+fn release_vfork(child: u32) -> bool {
+    let Some(parent) = VFORKED.lock().remove(&child) else {
+        return false;
+    };
+    let mut executor = EXECUTOR.lock();
+    if let Some(WaitReason::Vfork(waiting_on)) = executor.get_process(parent).and_then(|pcb| pcb.wait_reason.clone()) {
+        if waiting_on == child {
+            executor.unblock_process(parent);
+        }
+    }
+    true
+}
+
+/// An exiting vfork child lets its parent go on, and an exiting process
+/// takes its threads with it
+pub fn release_process(pid: u32) {
+    release_vfork(pid);
+    // A parent exiting first leaves nobody waiting
+    VFORKED.lock().retain(|_, parent| *parent != pid);
+
+    let threads: Vec<u32> = {
+        let mut threads = THREADS.lock();
+        let ended = threads.iter().filter(|(_, process)| **process == pid).map(|(tid, _)| *tid).collect();
+        threads.retain(|_, process| *process != pid);
+        ended
+    };
+    let mut executor = EXECUTOR.lock();
+    for tid in threads {
+        executor.terminate_process(tid, 0);
+    }
+}
@@ -6,12 +6,16 @@ pub mod elf;
 pub mod pe_loader;
 pub mod context_switch;
 pub mod executor;
+pub mod checkpoint;
+pub mod fork;
 
 use alloc::vec::Vec;
 use alloc::string::String;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use crate::serial_println;
+use crate::nt::object::Handle;
+use crate::nt::security::SECURITY_MANAGER;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ProcessId(pub u32);
@@ -35,6 +39,8 @@ pub struct Process {
     pub threads: Vec<ThreadId>,
     pub parent: Option<ProcessId>,
     pub children: Vec<ProcessId>,
+    // Primary security token
+    pub token: Handle,
 }
 
 impl Process {
@@ -46,6 +52,7 @@ impl Process {
             threads: Vec::new(),
             parent,
             children: Vec::new(),
+            token: Handle::NULL,
         }
     }
     
@@ -92,24 +99,46 @@ impl ProcessManager {
     }
 
     pub fn create_process(&mut self, name: String, parent: Option<ProcessId>) -> ProcessId {
+        let id = self.allocate_id();
+        self.add_process(id, name, parent);
+        id
+    }
+
+    /// A pid for a new process, with an address space of its own. The
+    /// executor's processes draw theirs from here too, so that both know a
+    /// process by the same one.
+    pub fn allocate_id(&mut self) -> ProcessId {
         let id = ProcessId(self.next_process_id);
         self.next_process_id += 1;
+        crate::memory::mmap::create_process(id.0);
+        id
+    }
 
+    /// Record a process under the pid `allocate_id` gave it
+    pub fn add_process(&mut self, id: ProcessId, name: String, parent: Option<ProcessId>) {
         let mut process = Process::new(id, name, parent);
         process.state = ProcessState::Ready;
         
-        // Update parent's children list if needed
+        // Update parent's children list if needed; children inherit the
+        // parent's token
         if let Some(parent_id) = parent {
             for p in &mut self.processes {
                 if p.id == parent_id {
                     p.add_child(id);
+                    process.token = p.token;
                     break;
                 }
             }
         }
+        if process.token == Handle::NULL {
+            process.token = SECURITY_MANAGER.lock().system_token();
+        }
         
         self.processes.push(process);
-        id
+        if let Some(parent_id) = parent {
+            crate::security::sandbox::inherit_sandbox(parent_id.0 as u64, id.0 as u64);
+            crate::container::inherit(parent_id.0, id.0);
+        }
     }
 
     pub fn get_process(&self, id: ProcessId) -> Option<&Process> {
@@ -129,6 +158,16 @@ impl ProcessManager {
     pub fn list_processes(&self) -> &Vec<Process> {
         &self.processes
     }
+
+    pub fn set_token(&mut self, id: ProcessId, token: Handle) -> bool {
+        match self.get_process_mut(id) {
+            Some(process) => {
+                process.token = token;
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 lazy_static! {
@@ -140,4 +179,19 @@ pub fn current_process() -> Option<Process> {
     // In a real implementation, this would return the currently running process
     // For now, return None to indicate kernel context
     None
-}
\ No newline at end of file
+}
+
+/// Primary token of the running process, or the SYSTEM token in kernel context
+pub fn current_token() -> Handle {
+    let token = {
+        let manager = PROCESS_MANAGER.lock();
+        manager
+            .current_process
+            .and_then(|id| manager.get_process(id))
+            .map(|process| process.token)
+    };
+    match token {
+        Some(token) if token != Handle::NULL => token,
+        _ => SECURITY_MANAGER.lock().system_token(),
+    }
+}
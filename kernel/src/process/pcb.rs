@@ -2,6 +2,7 @@
 use x86_64::{VirtAddr, structures::paging::PageTable};
 use alloc::{vec::Vec, string::String, boxed::Box};
 use crate::memory::PageProtection;
+use crate::security::mitigations::CetState;
 use core::mem::MaybeUninit;
 
 // FPU/SSE state for FXSAVE/FXRSTOR (512 bytes)
@@ -185,6 +186,14 @@ impl AddressSpace {
     pub fn add_region(&mut self, region: MemoryRegion) {
         self.regions.push(region);
     }
+    
+    /// Every range the process has memory in: its regions, heap and stack
+    pub fn ranges(&self) -> Vec<core::ops::Range<VirtAddr>> {
+        let mut ranges: Vec<_> = self.regions.iter().map(|region| region.start..region.end).collect();
+        ranges.push(self.heap_start..self.heap_end);
+        ranges.push(self.stack_end..self.stack_start);
+        ranges
+    }
 }
 
 // File descriptor for process
@@ -202,6 +211,9 @@ pub struct ProcessControlBlock {
     // Process identification
     pub pid: u32,
     pub ppid: Option<u32>,  // Parent PID
+    // A thread's process, whose memory, handles and identity its system
+    // calls act on; None for the process's first thread
+    pub thread_of: Option<u32>,
     pub name: String,
     pub command_line: String,
     
@@ -212,6 +224,7 @@ pub struct ProcessControlBlock {
     
     // Memory management
     pub address_space: AddressSpace,
+    pub oom_score_adj: i16,  // -1000 (never OOM-killed) to 1000
     
     // File descriptors
     pub file_descriptors: Vec<FileDescriptor>,
@@ -229,6 +242,7 @@ pub struct ProcessControlBlock {
     // Security
     pub uid: u32,
     pub gid: u32,
+    pub cet: CetState,
     
     // Statistics
     pub creation_time: u64,
@@ -239,11 +253,12 @@ pub struct ProcessControlBlock {
 #[derive(Debug, Clone)]
 pub enum WaitReason {
     None,
-    Sleep(u64),          // Sleep until timestamp
-    WaitPid(u32),        // Waiting for child process
+    Sleep(u64),          // Sleep until this clocksource time, in ns
+    WaitPid(u32),        // Waiting for a child process or thread to exit
     IO(i32),             // Waiting for I/O on file descriptor
     Mutex(usize),        // Waiting for mutex
     Signal,              // Waiting for signal
+    Vfork(u32),          // Lending memory to a vfork child until it execs or exits
 }
 
 impl ProcessControlBlock {
@@ -251,12 +266,14 @@ impl ProcessControlBlock {
         Self {
             pid,
             ppid: None,
+            thread_of: None,
             name,
             command_line,
             context: CpuContext::new(),
             kernel_stack: VirtAddr::new(0),
             user_stack: VirtAddr::new(0),
             address_space: AddressSpace::new(),
+            oom_score_adj: 0,
             file_descriptors: Vec::new(),
             next_fd: 3,  // 0=stdin, 1=stdout, 2=stderr
             priority: 10,  // Default priority
@@ -266,6 +283,7 @@ impl ProcessControlBlock {
             wait_reason: None,
             uid: 0,
             gid: 0,
+            cet: CetState::new(),
             creation_time: 0,  // Would get from timer
             user_time: 0,
             kernel_time: 0,
@@ -9,10 +9,19 @@ const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
 const IMAGE_FILE_MACHINE_I386: u16 = 0x014C;
 
 // PE characteristics
+const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
 const IMAGE_FILE_EXECUTABLE_IMAGE: u16 = 0x0002;
 const IMAGE_FILE_LARGE_ADDRESS_AWARE: u16 = 0x0020;
 const IMAGE_FILE_DLL: u16 = 0x2000;
 
+// DLL characteristics
+const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+
+// Base relocation types
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
 // Section characteristics
 const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
 const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
@@ -181,6 +190,12 @@ pub struct LoadedSection {
     pub characteristics: u32,
 }
 
+// `len` bytes of a mapped image at `rva`
+fn bytes_at(image: &mut [u8], rva: u64, len: usize) -> Option<&mut [u8]> {
+    let start = usize::try_from(rva).ok()?;
+    image.get_mut(start..start.checked_add(len)?)
+}
+
 #[derive(Debug)]
 pub struct ImportInfo {
     pub dll_name: String,
@@ -267,9 +282,36 @@ impl PeLoader {
             return Err("Not a PE32+ (64-bit) executable");
         }
         
+        // Images built with /DYNAMICBASE and a relocation table can be
+        // loaded anywhere; ASLR picks their base
+        let reloc_directory = Self::data_directory(
+            data,
+            opt_header_offset,
+            &opt_header,
+            DataDirectory::BaseRelocationTable,
+        );
+        let relocatable = reloc_directory.1 != 0
+            && coff_header.characteristics & IMAGE_FILE_RELOCS_STRIPPED == 0
+            && opt_header.dll_characteristics & IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE != 0;
+        let image_base = crate::security::aslr::randomize_image_base(
+            opt_header.image_base,
+            opt_header.size_of_image as u64,
+            relocatable,
+        );
+        
+        // Lay the image out as it will be mapped: headers, then each
+        // section's raw data at its RVA, zero-filled past it. Relocations
+        // may land anywhere in it, including uninitialized section tails.
+        let image_size = opt_header.size_of_image as usize;
+        let mut image = Vec::new();
+        image.try_reserve_exact(image_size).map_err(|_| "Image too large")?;
+        image.resize(image_size, 0u8);
+        let header_len = (opt_header.size_of_headers as usize).min(data.len()).min(image_size);
+        image[..header_len].copy_from_slice(&data[..header_len]);
+        
         // Parse sections
         let section_offset = opt_header_offset + coff_header.size_of_optional_header as usize;
-        let mut sections = Vec::new();
+        let mut headers = Vec::new();
         
         for i in 0..coff_header.number_of_sections {
             let section_header_offset = section_offset + (i as usize * core::mem::size_of::<SectionHeader>());
@@ -281,30 +323,55 @@ impl PeLoader {
                 *(data[section_header_offset..].as_ptr() as *const SectionHeader)
             };
             
-            // Get section name
-            let name_bytes = &section_header.name;
-            let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(8);
-            let name = String::from_utf8_lossy(&name_bytes[..name_len]).to_string();
-            
-            // Read section data
+            // Raw data beyond VirtualSize is file alignment padding
+            let raw_len = match section_header.virtual_size {
+                0 => section_header.size_of_raw_data,
+                size => section_header.size_of_raw_data.min(size),
+            } as usize;
             let data_start = section_header.pointer_to_raw_data as usize;
-            let data_end = data_start + section_header.size_of_raw_data as usize;
-            
-            let section_data = if data_start < data.len() && data_end <= data.len() {
-                data[data_start..data_end].to_vec()
-            } else {
-                Vec::new()
-            };
-            
-            sections.push(LoadedSection {
-                name,
-                virtual_address: VirtAddr::new(opt_header.image_base + section_header.virtual_address as u64),
-                virtual_size: section_header.virtual_size as usize,
-                data: section_data,
-                characteristics: section_header.characteristics,
-            });
+            let rva = section_header.virtual_address as usize;
+            if raw_len != 0 {
+                let src = data
+                    .get(data_start..data_start.saturating_add(raw_len))
+                    .ok_or("Section data outside file")?;
+                image
+                    .get_mut(rva..rva.saturating_add(raw_len))
+                    .ok_or("Section outside image")?
+                    .copy_from_slice(src);
+            }
+            headers.push(section_header);
         }
         
+        let delta = image_base.wrapping_sub(opt_header.image_base);
+        if delta != 0 {
+            Self::apply_relocations(&mut image, reloc_directory, delta)?;
+        }
+        
+        let sections = headers
+            .iter()
+            .map(|section_header| {
+                // Get section name
+                let name_bytes = &section_header.name;
+                let name_len = name_bytes.iter().position(|&b| b == 0).unwrap_or(8);
+                let name = String::from_utf8_lossy(&name_bytes[..name_len]).to_string();
+                
+                let rva = (section_header.virtual_address as usize).min(image_size);
+                let size = match section_header.virtual_size {
+                    0 => section_header.size_of_raw_data,
+                    size => size,
+                } as usize;
+                let end = rva.saturating_add(size).min(image_size);
+                
+                LoadedSection {
+                    name,
+                    virtual_address: VirtAddr::new(image_base + section_header.virtual_address as u64),
+                    virtual_size: section_header.virtual_size as usize,
+                    data: image[rva..end].to_vec(),
+                    characteristics: section_header.characteristics,
+                }
+            })
+            .collect();
+        
         // Parse imports (simplified)
         let imports = Vec::new(); // Would parse import table here
         
@@ -312,8 +379,8 @@ impl PeLoader {
         let exports = Vec::new(); // Would parse export table here
         
         Ok(LoadedPE {
-            entry_point: VirtAddr::new(opt_header.image_base + opt_header.address_of_entry_point as u64),
-            image_base: VirtAddr::new(opt_header.image_base),
+            entry_point: VirtAddr::new(image_base + opt_header.address_of_entry_point as u64),
+            image_base: VirtAddr::new(image_base),
             image_size: opt_header.size_of_image as usize,
             sections,
             imports,
@@ -322,6 +389,75 @@ impl PeLoader {
         })
     }
     
+    // (RVA, size) of a data directory, zero if the image has none
+    fn data_directory(
+        data: &[u8],
+        opt_header_offset: usize,
+        opt_header: &OptionalHeader64,
+        directory: DataDirectory,
+    ) -> (u32, u32) {
+        let index = directory as usize;
+        if index >= opt_header.number_of_rva_and_sizes as usize {
+            return (0, 0);
+        }
+        let offset = opt_header_offset + core::mem::size_of::<OptionalHeader64>() + index * 8;
+        match data.get(offset..offset + 8) {
+            Some(entry) => (
+                u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]),
+                u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            ),
+            None => (0, 0),
+        }
+    }
+    
+    /// Rebase an image loaded `delta` away from its preferred base, by the
+    /// relocation table in `directory`
+    pub(crate) fn apply_relocations(
+        image: &mut [u8],
+        directory: (u32, u32),
+        delta: u64,
+    ) -> Result<(), &'static str> {
+        let (rva, size) = directory;
+        let table = bytes_at(image, rva as u64, size as usize)
+            .ok_or("Relocation table outside image")?
+            .to_vec();
+        
+        let mut offset = 0;
+        while offset + 8 <= table.len() {
+            let page = u32::from_le_bytes([table[offset], table[offset + 1], table[offset + 2], table[offset + 3]]);
+            let block_size = u32::from_le_bytes([
+                table[offset + 4], table[offset + 5], table[offset + 6], table[offset + 7],
+            ]) as usize;
+            if block_size < 8 || offset + block_size > table.len() {
+                break;
+            }
+            
+            for entry in table[offset + 8..offset + block_size].chunks_exact(2) {
+                let entry = u16::from_le_bytes([entry[0], entry[1]]);
+                let target = page as u64 + (entry & 0xFFF) as u64;
+                match entry >> 12 {
+                    IMAGE_REL_BASED_ABSOLUTE => {}
+                    IMAGE_REL_BASED_DIR64 => {
+                        let bytes = bytes_at(image, target, 8).ok_or("Relocation target outside image")?;
+                        let value = u64::from_le_bytes([
+                            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+                        ]);
+                        bytes.copy_from_slice(&value.wrapping_add(delta).to_le_bytes());
+                    }
+                    IMAGE_REL_BASED_HIGHLOW => {
+                        let bytes = bytes_at(image, target, 4).ok_or("Relocation target outside image")?;
+                        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                        bytes.copy_from_slice(&value.wrapping_add(delta as u32).to_le_bytes());
+                    }
+                    _ => return Err("Unsupported relocation type"),
+                }
+            }
+            offset += block_size;
+        }
+        
+        Ok(())
+    }
+    
     pub fn validate_pe(data: &[u8]) -> bool {
         if data.len() < core::mem::size_of::<DosHeader>() {
             return false;
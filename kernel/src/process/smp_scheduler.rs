@@ -3,7 +3,7 @@ use super::thread::{Thread, ThreadState, THREAD_MANAGER};
 use alloc::vec::Vec;
 use alloc::collections::VecDeque;
 use core::sync::atomic::{AtomicU32, AtomicBool, Ordering};
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use crate::smp::{MAX_CPUS, percpu, ipi};
 
@@ -12,6 +12,8 @@ const MIN_TIME_SLICE: u32 = 1;
 const MAX_TIME_SLICE: u32 = 100;
 const LOAD_BALANCE_PERIOD: u32 = 100;
 
+crate::declare_trace_event!(pub TRACE_SCHED_SWITCH, "sched", "sched_switch", ["cpu", "prev_tid", "next_tid"]);
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SchedulerPolicy {
     RoundRobin,
@@ -162,7 +164,19 @@ impl SmpScheduler {
     }
 
     pub fn schedule(&self, cpu_id: u32) -> Option<ThreadId> {
+        // A CPU that gets here is not in a soft lockup
+        crate::debug::watchdog::touch();
+
         let current = self.current_threads[cpu_id as usize].load(Ordering::Relaxed);
+
+        // An offline CPU hands its thread on and runs nothing
+        if crate::smp::hotplug::is_parked(cpu_id) {
+            self.current_threads[cpu_id as usize].store(0, Ordering::Relaxed);
+            if current != 0 {
+                self.enqueue_thread(ThreadId(current), None);
+            }
+            return None;
+        }
         
         if current != 0 {
             let mut rq = self.run_queues[cpu_id as usize].lock();
@@ -172,6 +186,7 @@ impl SmpScheduler {
         let mut rq = self.run_queues[cpu_id as usize].lock();
         if let Some(next) = rq.dequeue() {
             self.current_threads[cpu_id as usize].store(next, Ordering::Relaxed);
+            TRACE_SCHED_SWITCH.emit(&[cpu_id as u64, current as u64, next.0 as u64]);
             
             percpu::clear_need_resched();
             
@@ -182,11 +197,15 @@ impl SmpScheduler {
         }
     }
 
+    /// Id of the thread running on a CPU, 0 for none
+    pub fn current_thread(&self, cpu_id: u32) -> u32 {
+        self.current_threads[cpu_id as usize].load(Ordering::Relaxed)
+    }
+
     pub fn enqueue_thread(&self, thread_id: ThreadId, cpu_affinity: Option<u32>) {
-        let target_cpu = if let Some(cpu) = cpu_affinity {
-            cpu
-        } else {
-            self.find_least_loaded_cpu()
+        let target_cpu = match cpu_affinity {
+            Some(cpu) if !crate::smp::hotplug::is_parked(cpu) => cpu,
+            _ => self.find_least_loaded_cpu(self.get_thread_affinity(thread_id)),
         };
         
         let mut rq = self.run_queues[target_cpu as usize].lock();
@@ -211,7 +230,18 @@ impl SmpScheduler {
         percpu::set_need_resched();
     }
 
-    pub fn set_thread_affinity(&self, thread_id: ThreadId, cpu_mask: u64) {
+    /// Restrict a thread to the CPUs in `cpu_mask`, moving it if it is
+    /// queued elsewhere. False if there is no such thread or the mask has
+    /// no online CPU.
+    pub fn set_thread_affinity(&self, thread_id: ThreadId, cpu_mask: u64) -> bool {
+        if self.find_first_cpu_in_mask(cpu_mask).is_none() {
+            return false;
+        }
+        match THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+            Some(thread) => thread.cpu_affinity = cpu_mask,
+            None => return false,
+        }
+
         let current_cpu = self.find_thread_cpu(thread_id);
         
         if let Some(current) = current_cpu {
@@ -222,6 +252,7 @@ impl SmpScheduler {
                 }
             }
         }
+        true
     }
 
     pub fn get_thread_affinity(&self, thread_id: ThreadId) -> u64 {
@@ -233,11 +264,53 @@ impl SmpScheduler {
         }
     }
 
-    fn find_least_loaded_cpu(&self) -> u32 {
+    /// Move every thread queued on `cpu`, which is going offline, to CPUs
+    /// their affinity allows. A thread whose mask allows no other CPU loses
+    /// its affinity rather than never running. Returns how many moved.
+    pub fn drain_cpu(&self, cpu_id: u32) -> usize {
+        let threads: Vec<ThreadId> = {
+            let mut guard = self.run_queues[cpu_id as usize].lock();
+            let rq = &mut *guard;
+            let threads = rq.rt_queue.drain(..)
+                .chain(rq.ready_queue.drain(..))
+                .chain(rq.expired_queue.drain(..))
+                .collect();
+            rq.nr_running.store(0, Ordering::Relaxed);
+            threads
+        };
+
+        for &thread_id in &threads {
+            let mask = self.get_thread_affinity(thread_id);
+            if self.find_first_cpu_in_mask(mask).is_none() {
+                crate::pr_warn!("Thread {} has no online CPU left in its affinity mask, allowing any", thread_id.0);
+                if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+                    thread.cpu_affinity = !0;
+                }
+            }
+            self.enqueue_thread(thread_id, None);
+        }
+        threads.len()
+    }
+
+    // CPUs that may run threads
+    fn online_cpus(&self) -> Vec<u32> {
+        crate::smp::SMP_MANAGER
+            .lock()
+            .get_online_cpus()
+            .into_iter()
+            .filter(|&cpu| !crate::smp::hotplug::is_parked(cpu))
+            .collect()
+    }
+
+    fn allowed(&self, thread_id: ThreadId, cpu_id: u32) -> bool {
+        cpu_id >= 64 || self.get_thread_affinity(thread_id) & (1 << cpu_id) != 0
+    }
+
+    fn find_least_loaded_cpu(&self, cpu_mask: u64) -> u32 {
         let mut min_load = u32::MAX;
         let mut best_cpu = 0;
         
-        for cpu in 0..crate::smp::SMP_MANAGER.lock().online_cpu_count() {
+        for cpu in self.online_cpus().into_iter().filter(|&cpu| cpu >= 64 || cpu_mask & (1 << cpu) != 0) {
             let rq = self.run_queues[cpu as usize].lock();
             let load = rq.load();
             if load < min_load {
@@ -264,7 +337,7 @@ impl SmpScheduler {
     fn find_first_cpu_in_mask(&self, cpu_mask: u64) -> Option<u32> {
         for cpu in 0..64 {
             if (cpu_mask & (1 << cpu)) != 0 {
-                if crate::smp::cpu_online(cpu) {
+                if crate::smp::cpu_online(cpu) && !crate::smp::hotplug::is_parked(cpu) {
                     return Some(cpu);
                 }
             }
@@ -292,7 +365,7 @@ impl SmpScheduler {
         drop(local_rq);
         
         if local_load == 0 {
-            for other_cpu in 0..crate::smp::SMP_MANAGER.lock().online_cpu_count() {
+            for other_cpu in self.online_cpus() {
                 if other_cpu == cpu_id {
                     continue;
                 }
@@ -310,7 +383,7 @@ impl SmpScheduler {
             let avg_load = self.calculate_average_load();
             
             if local_load > avg_load + 1 {
-                for other_cpu in 0..crate::smp::SMP_MANAGER.lock().online_cpu_count() {
+                for other_cpu in self.online_cpus() {
                     if other_cpu == cpu_id {
                         continue;
                     }
@@ -330,9 +403,10 @@ impl SmpScheduler {
 
     fn calculate_average_load(&self) -> u32 {
         let mut total_load = 0;
-        let cpu_count = crate::smp::SMP_MANAGER.lock().online_cpu_count();
+        let cpus = self.online_cpus();
+        let cpu_count = cpus.len() as u32;
         
-        for cpu in 0..cpu_count {
+        for cpu in cpus {
             let rq = self.run_queues[cpu as usize].lock();
             total_load += rq.load();
         }
@@ -344,10 +418,16 @@ impl SmpScheduler {
         }
     }
 
+    // The last ready thread in `rq` allowed to run on `to_cpu`
+    fn take_movable(&self, rq: &mut RunQueue, to_cpu: u32) -> Option<ThreadId> {
+        let pos = rq.ready_queue.iter().rposition(|&thread_id| self.allowed(thread_id, to_cpu))?;
+        rq.nr_running.fetch_sub(1, Ordering::Relaxed);
+        rq.ready_queue.remove(pos)
+    }
+
     fn pull_task(&self, to_cpu: u32, from_cpu: u32) {
         let mut from_rq = self.run_queues[from_cpu as usize].lock();
-        if let Some(thread_id) = from_rq.ready_queue.pop_back() {
-            from_rq.nr_running.fetch_sub(1, Ordering::Relaxed);
+        if let Some(thread_id) = self.take_movable(&mut from_rq, to_cpu) {
             drop(from_rq);
             
             let mut to_rq = self.run_queues[to_cpu as usize].lock();
@@ -357,8 +437,7 @@ impl SmpScheduler {
 
     fn push_task(&self, from_cpu: u32, to_cpu: u32) {
         let mut from_rq = self.run_queues[from_cpu as usize].lock();
-        if let Some(thread_id) = from_rq.ready_queue.pop_back() {
-            from_rq.nr_running.fetch_sub(1, Ordering::Relaxed);
+        if let Some(thread_id) = self.take_movable(&mut from_rq, to_cpu) {
             drop(from_rq);
             
             let mut to_rq = self.run_queues[to_cpu as usize].lock();
@@ -379,6 +458,7 @@ pub fn schedule() {
     let cpu_id = percpu::get_cpu_id();
     
     if let Some(next_thread) = SMP_SCHEDULER.schedule(cpu_id) {
+        crate::win32::thread::activate_thread(next_thread);
         crate::process::context_switch::switch_to_thread(next_thread);
     }
 }
@@ -387,6 +467,7 @@ pub fn tick() {
     let cpu_id = percpu::get_cpu_id();
     
     if let Some(next_thread) = SMP_SCHEDULER.tick(cpu_id) {
+        crate::win32::thread::activate_thread(next_thread);
         crate::process::context_switch::switch_to_thread(next_thread);
     }
 }
@@ -405,6 +486,10 @@ pub fn dequeue_thread(thread_id: ThreadId) {
     SMP_SCHEDULER.dequeue_thread(thread_id);
 }
 
-pub fn set_thread_cpu_affinity(thread_id: ThreadId, cpu_mask: u64) {
-    SMP_SCHEDULER.set_thread_affinity(thread_id, cpu_mask);
+pub fn set_thread_cpu_affinity(thread_id: ThreadId, cpu_mask: u64) -> bool {
+    SMP_SCHEDULER.set_thread_affinity(thread_id, cpu_mask)
+}
+
+pub fn get_thread_cpu_affinity(thread_id: ThreadId) -> u64 {
+    SMP_SCHEDULER.get_thread_affinity(thread_id)
 }
\ No newline at end of file
@@ -11,6 +11,14 @@ pub enum ThreadState {
     Terminated,
 }
 
+// Argument registers loaded the first time a thread is switched to, so its
+// entry point receives them as the first two Win64 arguments
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InitialRegisters {
+    pub rcx: u64,
+    pub rdx: u64,
+}
+
 #[derive(Debug)]
 pub struct Thread {
     pub id: ThreadId,
@@ -18,6 +26,7 @@ pub struct Thread {
     pub state: ThreadState,
     pub stack_pointer: u64,
     pub instruction_pointer: u64,
+    pub initial_registers: InitialRegisters,
     pub priority: u8,
     pub cpu_affinity: u64,
     pub current_cpu: Option<u32>,
@@ -31,6 +40,7 @@ impl Thread {
             state: ThreadState::Ready,
             stack_pointer: 0,
             instruction_pointer: 0,
+            initial_registers: InitialRegisters::default(),
             priority: 0,
             cpu_affinity: !0u64,
             current_cpu: None,
@@ -0,0 +1,54 @@
+// Bitmap updates
+//
+// What changed goes out uncompressed, in pieces small enough that an
+// update PDU holding one stays within an MCS send data PDU. A piece is a
+// bitmap of rows from the bottom up, as wide as a multiple of four
+// pixels, in the session's color depth.
+
+use alloc::vec::Vec;
+
+use crate::vnc::screen::{Region, Screen};
+
+const PIECE_WIDTH: usize = 64;
+const PIECE_HEIGHT: usize = 32;
+
+/// A region cut into pieces
+pub fn pieces(region: Region) -> impl Iterator<Item = Region> {
+    let (right, bottom) = (region.x + region.width, region.y + region.height);
+    (region.y..bottom).step_by(PIECE_HEIGHT).flat_map(move |y| {
+        (region.x..right).step_by(PIECE_WIDTH).map(move |x| Region {
+            x,
+            y,
+            width: PIECE_WIDTH.min(right - x),
+            height: PIECE_HEIGHT.min(bottom - y),
+        })
+    })
+}
+
+/// A piece of the screen as a TS_BITMAP_DATA
+pub fn rectangle(out: &mut Vec<u8>, screen: &Screen, piece: Region, bpp: u16) {
+    // The padding repeats the last pixel of each row
+    let width = piece.width.next_multiple_of(4);
+    let length = width * piece.height * (bpp as usize).div_ceil(8);
+    let (right, bottom) = (piece.x + piece.width - 1, piece.y + piece.height - 1);
+    for value in [piece.x, piece.y, right, bottom, width, piece.height, bpp as usize, 0, length] {
+        out.extend_from_slice(&(value as u16).to_le_bytes());
+    }
+    out.reserve(length);
+    for y in (piece.y..piece.y + piece.height).rev() {
+        let row = &screen.pixels[y * screen.width + piece.x..][..piece.width];
+        for x in 0..width {
+            write_pixel(out, row[x.min(piece.width - 1)], bpp);
+        }
+    }
+}
+
+fn write_pixel(out: &mut Vec<u8>, pixel: u32, bpp: u16) {
+    let (red, green, blue) = (pixel >> 16 & 0xFF, pixel >> 8 & 0xFF, pixel & 0xFF);
+    match bpp {
+        15 => out.extend_from_slice(&((red >> 3) << 10 | (green >> 3) << 5 | blue >> 3).to_le_bytes()[..2]),
+        16 => out.extend_from_slice(&((red >> 3) << 11 | (green >> 2) << 5 | blue >> 3).to_le_bytes()[..2]),
+        24 => out.extend_from_slice(&[blue as u8, green as u8, red as u8]),
+        _ => out.extend_from_slice(&[blue as u8, green as u8, red as u8, 0xFF]),
+    }
+}
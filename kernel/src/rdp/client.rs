@@ -0,0 +1,756 @@
+// One client's connection
+//
+// The connection sequence: X.224 and MCS connect, the user attached and
+// the channels joined, the client random exchanged for the session keys,
+// the client's logon checked, licensing passed over and the capabilities
+// exchanged. Then input as it arrives, and bitmap updates of what changed
+// once what was sent has drained. Keys and the pointer go to the input
+// queue and typed characters to the shell, as for VNC.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::input::{self, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_ABS, EV_REL, REL_WHEEL};
+use crate::net::tcp::{self, TcpState};
+use crate::nt::object::Handle;
+use crate::security::accounts;
+use crate::time::clocksource::now_ns;
+use crate::vnc::keysym;
+use crate::vnc::screen::{self, Region, Screen};
+
+use super::bitmap;
+use super::mcs::{self, Domain, Reader, Tpdu, FIRST_STATIC_CHANNEL, IO_CHANNEL, USER_CHANNEL};
+use super::scancode;
+use super::security::{ServerKey, Session, ENCRYPTION_128BIT, ENCRYPTION_40BIT, ENCRYPTION_56BIT, RANDOM_LENGTH};
+
+// Most taken from the receive buffer at once
+const RECEIVE_CHUNK: usize = 4096;
+// Updates are sent no more often than this
+const UPDATE_INTERVAL_NS: u64 = 40_000_000;
+// A client has this long to get through the connection sequence
+const HANDSHAKE_TIMEOUT_NS: u64 = 30_000_000_000;
+// Bitmap data in one update PDU, leaving room for the headers around it
+// within an MCS send data PDU
+const MAX_UPDATE: usize = 15_000;
+const SHARE_ID: u32 = 0x0001_03EA;
+
+// Client and server data blocks
+const CS_CORE: u16 = 0xC001;
+const CS_SECURITY: u16 = 0xC002;
+const CS_NET: u16 = 0xC003;
+const SC_CORE: u16 = 0x0C01;
+const SC_SECURITY: u16 = 0x0C02;
+const SC_NET: u16 = 0x0C03;
+const RDP_VERSION_5_PLUS: u32 = 0x0008_0004;
+const RNS_UD_32BPP_SUPPORT: u16 = 0x0008;
+const RNS_UD_CS_WANT_32BPP_SESSION: u16 = 0x0002;
+const ENCRYPTION_LEVEL_CLIENT_COMPATIBLE: u32 = 2;
+
+// Security header flags
+const SEC_EXCHANGE_PKT: u16 = 0x0001;
+const SEC_ENCRYPT: u16 = 0x0008;
+const SEC_INFO_PKT: u16 = 0x0040;
+const SEC_LICENSE_PKT: u16 = 0x0080;
+const SEC_SECURE_CHECKSUM: u16 = 0x0800;
+
+const INFO_UNICODE: u32 = 0x0010;
+
+// A license error saying the client needs no license
+const ERROR_ALERT: u8 = 0xFF;
+const PREAMBLE_VERSION_3_0: u8 = 0x03;
+const STATUS_VALID_CLIENT: u32 = 0x07;
+const ST_NO_TRANSITION: u32 = 0x02;
+const BB_ERROR_BLOB: u16 = 0x0004;
+
+// Share control PDU types
+const PDUTYPE_DEMANDACTIVEPDU: u16 = 0x1;
+const PDUTYPE_CONFIRMACTIVEPDU: u16 = 0x3;
+const PDUTYPE_DEACTIVATEALLPDU: u16 = 0x6;
+const PDUTYPE_DATAPDU: u16 = 0x7;
+const PROTOCOL_VERSION: u16 = 0x10;
+// A flow control PDU's length field
+const FLOW_MARKER: u16 = 0x8000;
+
+// Share data PDU types
+const PDUTYPE2_UPDATE: u8 = 0x02;
+const PDUTYPE2_CONTROL: u8 = 0x14;
+const PDUTYPE2_INPUT: u8 = 0x1C;
+const PDUTYPE2_SYNCHRONIZE: u8 = 0x1F;
+const PDUTYPE2_SHUTDOWN_REQUEST: u8 = 0x24;
+const PDUTYPE2_FONTLIST: u8 = 0x27;
+const PDUTYPE2_FONTMAP: u8 = 0x28;
+const PDUTYPE2_SET_ERROR_INFO: u8 = 0x2F;
+const STREAM_LOW: u8 = 1;
+const PACKET_COMPRESSED: u8 = 0x20;
+
+const CTRLACTION_REQUEST_CONTROL: u16 = 1;
+const CTRLACTION_GRANTED_CONTROL: u16 = 2;
+const CTRLACTION_COOPERATE: u16 = 4;
+
+const UPDATETYPE_BITMAP: u16 = 1;
+
+// Why a client was turned away, as it tells its user
+const ERRINFO_SERVER_DENIED_CONNECTION: u32 = 0x07;
+const ERRINFO_SERVER_INSUFFICIENT_PRIVILEGES: u32 = 0x09;
+
+// Capability sets
+const CAPSTYPE_GENERAL: u16 = 0x01;
+const CAPSTYPE_BITMAP: u16 = 0x02;
+const CAPSTYPE_ORDER: u16 = 0x03;
+const CAPSTYPE_POINTER: u16 = 0x08;
+const CAPSTYPE_SHARE: u16 = 0x09;
+const CAPSTYPE_INPUT: u16 = 0x0D;
+const CAPSTYPE_FONT: u16 = 0x0E;
+const CAPSTYPE_VIRTUALCHANNEL: u16 = 0x14;
+const LONG_CREDENTIALS_SUPPORTED: u16 = 0x0004;
+const NO_BITMAP_COMPRESSION_HDR: u16 = 0x0400;
+const NEGOTIATEORDERSUPPORT: u16 = 0x0002;
+const ZEROBOUNDSDELTASSUPPORT: u16 = 0x0008;
+const INPUT_FLAG_SCANCODES: u16 = 0x0001;
+const INPUT_FLAG_UNICODE: u16 = 0x0010;
+const FONTSUPPORT_FONTLIST: u16 = 0x0001;
+
+// Input events
+const INPUT_EVENT_SCANCODE: u16 = 0x0004;
+const INPUT_EVENT_UNICODE: u16 = 0x0005;
+const INPUT_EVENT_MOUSE: u16 = 0x8001;
+const KBDFLAGS_EXTENDED: u16 = 0x0100;
+const KBDFLAGS_RELEASE: u16 = 0x8000;
+const PTRFLAGS_WHEEL_NEGATIVE: u16 = 0x0100;
+const PTRFLAGS_WHEEL: u16 = 0x0200;
+const PTRFLAGS_DOWN: u16 = 0x8000;
+const PTRFLAGS_BUTTON1: u16 = 0x1000;
+const PTRFLAGS_BUTTON2: u16 = 0x2000;
+const PTRFLAGS_BUTTON3: u16 = 0x4000;
+
+enum Phase {
+    // Waiting for the X.224 connection request
+    Request,
+    // Waiting for the MCS Connect Initial
+    Initial,
+    // Attaching the user and joining channels, until the client random
+    Channels,
+    // Waiting for the client's logon
+    Logon,
+    // Capabilities and connection finalization
+    Activating,
+    Running,
+    // Closing once what is queued has gone, for the reason given
+    Closing(&'static str),
+}
+
+/// What the server hands every client as it polls them
+pub struct Context<'a> {
+    pub key: &'a ServerKey,
+    pub keyboard: usize,
+    pub pointer: usize,
+    /// The screen now, captured for the first client ready for an update
+    pub screen: &'a mut Option<Screen>,
+    /// Characters typed, for the shell once the server is unlocked
+    pub typed: &'a mut String,
+}
+
+pub struct Client {
+    pub id: u32,
+    conn: u64,
+    pub address: String,
+    pub connected_at: u64,
+    /// The name the client computer gave
+    pub name: String,
+    /// The account the client logged on as
+    pub user: Option<String>,
+    token: Option<Handle>,
+    phase: Phase,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    sent: usize,
+    pub bytes_sent: u64,
+    requested_protocols: u32,
+    method: u32,
+    channels: Vec<u16>,
+    server_random: [u8; RANDOM_LENGTH],
+    session: Option<Session>,
+    /// Color depth of the session
+    pub bpp: u16,
+    // The screen size the client was last told
+    size: (usize, usize),
+    // What the client's copy of the screen holds
+    shadow: Option<Screen>,
+    last_update: u64,
+    shift: bool,
+    control: bool,
+}
+
+impl Client {
+    pub fn new(id: u32, conn: u64) -> Client {
+        let address = match tcp::peer(conn) {
+            Some((address, port)) => format!("{}:{}", address, port),
+            None => String::from("?"),
+        };
+        let mut server_random = [0u8; RANDOM_LENGTH];
+        crate::crypto::rng::fill_bytes(&mut server_random);
+        Client {
+            id,
+            conn,
+            address,
+            connected_at: now_ns(),
+            name: String::new(),
+            user: None,
+            token: None,
+            phase: Phase::Request,
+            input: Vec::new(),
+            output: Vec::new(),
+            sent: 0,
+            bytes_sent: 0,
+            requested_protocols: 0,
+            method: 0,
+            channels: Vec::new(),
+            server_random,
+            session: None,
+            bpp: 16,
+            size: (0, 0),
+            shadow: None,
+            last_update: 0,
+            shift: false,
+            control: false,
+        }
+    }
+
+    pub fn authenticated(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Session key length in bits, once there is a session
+    pub fn encryption(&self) -> Option<u32> {
+        self.session.as_ref().map(Session::bits)
+    }
+
+    /// Take what has arrived, answer it, and send what is queued; an
+    /// error ends the connection
+    pub fn poll(&mut self, context: &mut Context) -> Result<(), &'static str> {
+        match tcp::state(self.conn) {
+            Some(TcpState::Established) => {}
+            _ => return Err("connection closed"),
+        }
+        let running = matches!(self.phase, Phase::Running);
+        if !running && now_ns().saturating_sub(self.connected_at) > HANDSHAKE_TIMEOUT_NS {
+            return Err("connection sequence timed out");
+        }
+        if !matches!(self.phase, Phase::Closing(_)) {
+            let mut input = core::mem::take(&mut self.input);
+            input.extend_from_slice(&tcp::recv(self.conn, RECEIVE_CHUNK)?);
+            let mut used = 0;
+            while let Some(length) = mcs::frame_length(&input[used..])? {
+                self.frame(&input[used..used + length], context)?;
+                used += length;
+                if matches!(self.phase, Phase::Closing(_)) {
+                    break;
+                }
+            }
+            input.drain(..used);
+            self.input = input;
+        }
+        if matches!(self.phase, Phase::Running)
+            && self.sent == self.output.len()
+            && now_ns().saturating_sub(self.last_update) >= UPDATE_INTERVAL_NS
+        {
+            self.update(context);
+        }
+        self.flush();
+        match self.phase {
+            Phase::Closing(reason) if self.sent == self.output.len() => Err(reason),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+            return;
+        }
+        let window = tcp::send_window(self.conn).min(self.output.len() - self.sent);
+        if window > 0 && tcp::send(self.conn, &self.output[self.sent..self.sent + window]).is_ok() {
+            self.sent += window;
+            self.bytes_sent += window as u64;
+        }
+    }
+
+    fn frame(&mut self, frame: &[u8], context: &mut Context) -> Result<(), &'static str> {
+        match (mcs::tpdu(frame)?, &self.phase) {
+            (Tpdu::ConnectionRequest(request), Phase::Request) => {
+                let requested = mcs::requested_protocols(request);
+                self.requested_protocols = requested.unwrap_or(0);
+                mcs::connection_confirm(&mut self.output, requested.is_some());
+                self.phase = Phase::Initial;
+                Ok(())
+            }
+            (Tpdu::Data(data), Phase::Initial) => self.connect_initial(data, context.key),
+            (Tpdu::Data(_), Phase::Request) | (Tpdu::ConnectionRequest(_), _) => Err("unexpected X.224 TPDU"),
+            (Tpdu::Disconnect, _) => Err("client disconnected"),
+            (Tpdu::Data(data), _) => match mcs::domain_pdu(data)? {
+                Domain::ErectDomain => Ok(()),
+                Domain::AttachUser => {
+                    mcs::attach_user_confirm(&mut self.output);
+                    Ok(())
+                }
+                Domain::ChannelJoin(channel) => {
+                    if channel != USER_CHANNEL && channel != IO_CHANNEL && !self.channels.contains(&channel) {
+                        return Err("join of an unknown channel");
+                    }
+                    mcs::channel_join_confirm(&mut self.output, channel);
+                    Ok(())
+                }
+                Domain::SendData { channel, data } => self.send_data(channel, data, context),
+                Domain::Disconnect => Err("client disconnected"),
+            },
+        }
+    }
+
+    // The client's data blocks, answered with the server's
+    fn connect_initial(&mut self, pdu: &[u8], key: &ServerKey) -> Result<(), &'static str> {
+        let mut offered = 0;
+        for (kind, block) in mcs::blocks(mcs::connect_initial(pdu)?)? {
+            let mut reader = Reader::new(block);
+            match kind {
+                CS_CORE => self.core_data(block)?,
+                CS_SECURITY => {
+                    // The second set is for French clients, whose first is empty
+                    let methods = reader.u32_le()?;
+                    offered = if methods != 0 { methods } else { reader.u32_le().unwrap_or(0) };
+                }
+                CS_NET => {
+                    let count = reader.u32_le()? as u16;
+                    self.channels = (0..count).map(|i| FIRST_STATIC_CHANNEL + i).collect();
+                }
+                _ => {}
+            }
+        }
+        self.method = [ENCRYPTION_128BIT, ENCRYPTION_56BIT, ENCRYPTION_40BIT]
+            .into_iter()
+            .find(|&method| offered & method != 0)
+            .ok_or("no encryption method in common")?;
+        self.connect_response(key);
+        self.phase = Phase::Channels;
+        Ok(())
+    }
+
+    fn core_data(&mut self, block: &[u8]) -> Result<(), &'static str> {
+        let u16_at = |at: usize| block.get(at..at + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+        let name = block.get(20..52).ok_or("client core data truncated")?;
+        let units = name.chunks(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])).take_while(|&unit| unit != 0);
+        self.name = char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect();
+        // Optional fields, in the order of the versions that added them
+        let high = u16_at(136).unwrap_or(16);
+        let supported = u16_at(138).unwrap_or(0);
+        let early = u16_at(140).unwrap_or(0);
+        self.bpp = if early & RNS_UD_CS_WANT_32BPP_SESSION != 0 && supported & RNS_UD_32BPP_SUPPORT != 0 {
+            32
+        } else if matches!(high, 15 | 16 | 24) {
+            high
+        } else {
+            16
+        };
+        Ok(())
+    }
+
+    // The server's data blocks: its version, the channels' IDs, and the
+    // encryption with the server random and certificate
+    fn connect_response(&mut self, key: &ServerKey) {
+        let mut blocks = Vec::new();
+        let mut core = Vec::new();
+        core.extend_from_slice(&RDP_VERSION_5_PLUS.to_le_bytes());
+        core.extend_from_slice(&self.requested_protocols.to_le_bytes());
+        core.extend_from_slice(&0u32.to_le_bytes());
+        mcs::block(&mut blocks, SC_CORE, &core);
+
+        let mut net = Vec::new();
+        net.extend_from_slice(&IO_CHANNEL.to_le_bytes());
+        net.extend_from_slice(&(self.channels.len() as u16).to_le_bytes());
+        self.channels.iter().for_each(|channel| net.extend_from_slice(&channel.to_le_bytes()));
+        if self.channels.len() % 2 == 1 {
+            net.extend_from_slice(&[0, 0]);
+        }
+        mcs::block(&mut blocks, SC_NET, &net);
+
+        let certificate = key.certificate();
+        let mut security = Vec::new();
+        for value in [self.method, ENCRYPTION_LEVEL_CLIENT_COMPATIBLE, RANDOM_LENGTH as u32, certificate.len() as u32] {
+            security.extend_from_slice(&value.to_le_bytes());
+        }
+        security.extend_from_slice(&self.server_random);
+        security.extend_from_slice(certificate);
+        mcs::block(&mut blocks, SC_SECURITY, &security);
+        mcs::connect_response(&mut self.output, &blocks);
+    }
+
+    fn send_data(&mut self, channel: u16, data: &[u8], context: &mut Context) -> Result<(), &'static str> {
+        let mut reader = Reader::new(data);
+        let flags = reader.u16_le()?;
+        reader.skip(2)?;
+        if flags & SEC_EXCHANGE_PKT != 0 {
+            if !matches!(self.phase, Phase::Channels) {
+                return Err("unexpected security exchange");
+            }
+            let length = reader.u32_le()? as usize;
+            let client_random = context.key.client_random(reader.take(length)?)?;
+            self.session = Some(Session::new(self.method, &client_random, &self.server_random));
+            self.phase = Phase::Logon;
+            return Ok(());
+        }
+        let session = self.session.as_mut().ok_or("data before the security exchange")?;
+        if flags & SEC_ENCRYPT == 0 {
+            return Err("unencrypted PDU");
+        }
+        let signature = reader.take(8)?;
+        let mut payload = reader.rest().to_vec();
+        session.decrypt(&mut payload, signature, flags & SEC_SECURE_CHECKSUM != 0)?;
+        // Virtual channels are joined but nothing is served on them
+        if channel != IO_CHANNEL {
+            return Ok(());
+        }
+        if flags & SEC_INFO_PKT != 0 {
+            return self.logon(&payload, context);
+        }
+        if flags & SEC_LICENSE_PKT != 0 {
+            return Ok(());
+        }
+        self.pdu(&payload, context)
+    }
+
+    // The Client Info PDU: the user name and password, which must be an
+    // administrator's
+    fn logon(&mut self, info: &[u8], context: &mut Context) -> Result<(), &'static str> {
+        if !matches!(self.phase, Phase::Logon) {
+            return Err("unexpected client info");
+        }
+        let mut reader = Reader::new(info);
+        reader.skip(4)?;
+        let flags = reader.u32_le()?;
+        let domain = reader.u16_le()? as usize;
+        let user = reader.u16_le()? as usize;
+        let password = reader.u16_le()? as usize;
+        reader.skip(4)?;
+        // Each followed by a null
+        let unicode = flags & INFO_UNICODE != 0;
+        let mut text = |length: usize| -> Result<String, &'static str> {
+            let bytes = reader.take(length + if unicode { 2 } else { 1 })?;
+            let bytes = &bytes[..length];
+            if !unicode {
+                return Ok(bytes.iter().map(|&byte| byte as char).collect());
+            }
+            let units = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+            Ok(char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect())
+        };
+        text(domain)?;
+        let user = text(user)?;
+        let password = text(password)?;
+
+        let denied = match accounts::logon_user(&user, &password) {
+            Ok(token) if accounts::token_is_admin(token) => {
+                self.token = Some(token);
+                None
+            }
+            Ok(token) => {
+                accounts::logoff(token);
+                Some((ERRINFO_SERVER_INSUFFICIENT_PRIVILEGES, "not an administrator"))
+            }
+            Err(_) => Some((ERRINFO_SERVER_DENIED_CONNECTION, "logon failed")),
+        };
+        if let Some((error, reason)) = denied {
+            crate::pr_info!("rdp: {} could not log on as '{}': {}", self.address, user, reason);
+            self.share_data(PDUTYPE2_SET_ERROR_INFO, &error.to_le_bytes());
+            mcs::disconnect(&mut self.output);
+            self.phase = Phase::Closing(reason);
+            return Ok(());
+        }
+        self.user = Some(user);
+        self.license();
+        self.demand_active(context);
+        Ok(())
+    }
+
+    fn license(&mut self) {
+        let mut pdu = Vec::new();
+        pdu.extend_from_slice(&SEC_LICENSE_PKT.to_le_bytes());
+        pdu.extend_from_slice(&0u16.to_le_bytes());
+        pdu.extend_from_slice(&[ERROR_ALERT, PREAMBLE_VERSION_3_0]);
+        pdu.extend_from_slice(&16u16.to_le_bytes());
+        pdu.extend_from_slice(&STATUS_VALID_CLIENT.to_le_bytes());
+        pdu.extend_from_slice(&ST_NO_TRANSITION.to_le_bytes());
+        pdu.extend_from_slice(&BB_ERROR_BLOB.to_le_bytes());
+        pdu.extend_from_slice(&0u16.to_le_bytes());
+        mcs::send_data_indication(&mut self.output, IO_CHANNEL, &pdu);
+    }
+
+    // Send a PDU on the I/O channel, encrypted
+    fn send(&mut self, pdu: &[u8]) {
+        let Some(session) = self.session.as_mut() else { return };
+        let mut data = pdu.to_vec();
+        let signature = session.encrypt(&mut data);
+        let mut secured = Vec::with_capacity(12 + data.len());
+        secured.extend_from_slice(&SEC_ENCRYPT.to_le_bytes());
+        secured.extend_from_slice(&0u16.to_le_bytes());
+        secured.extend_from_slice(&signature);
+        secured.extend_from_slice(&data);
+        mcs::send_data_indication(&mut self.output, IO_CHANNEL, &secured);
+    }
+
+    fn share_control(&mut self, pdu_type: u16, body: &[u8]) {
+        let mut pdu = Vec::with_capacity(6 + body.len());
+        pdu.extend_from_slice(&((6 + body.len()) as u16).to_le_bytes());
+        pdu.extend_from_slice(&(pdu_type | PROTOCOL_VERSION).to_le_bytes());
+        pdu.extend_from_slice(&USER_CHANNEL.to_le_bytes());
+        pdu.extend_from_slice(body);
+        self.send(&pdu);
+    }
+
+    fn share_data(&mut self, pdu_type: u8, body: &[u8]) {
+        let mut data = Vec::with_capacity(12 + body.len());
+        data.extend_from_slice(&SHARE_ID.to_le_bytes());
+        data.extend_from_slice(&[0, STREAM_LOW]);
+        // What follows the share control header's and this header's first
+        // eight bytes
+        data.extend_from_slice(&((4 + body.len()) as u16).to_le_bytes());
+        data.extend_from_slice(&[pdu_type, 0, 0, 0]);
+        data.extend_from_slice(body);
+        self.share_control(PDUTYPE_DATAPDU, &data);
+    }
+
+    // The server's capabilities, at the screen's size
+    fn demand_active(&mut self, context: &mut Context) {
+        let screen = context.screen.get_or_insert_with(screen::capture);
+        self.size = (screen.width, screen.height);
+        let (count, capabilities) = capability_sets(self.bpp, self.size);
+        let mut body = Vec::new();
+        body.extend_from_slice(&SHARE_ID.to_le_bytes());
+        body.extend_from_slice(&4u16.to_le_bytes());
+        body.extend_from_slice(&((4 + capabilities.len()) as u16).to_le_bytes());
+        body.extend_from_slice(b"RDP\0");
+        body.extend_from_slice(&count.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&capabilities);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        self.share_control(PDUTYPE_DEMANDACTIVEPDU, &body);
+        self.shadow = None;
+        self.phase = Phase::Activating;
+    }
+
+    // A share control PDU from the client
+    fn pdu(&mut self, pdu: &[u8], context: &mut Context) -> Result<(), &'static str> {
+        let mut reader = Reader::new(pdu);
+        if reader.u16_le()? == FLOW_MARKER {
+            return Ok(());
+        }
+        let pdu_type = reader.u16_le()? & 0xF;
+        reader.skip(2)?;
+        match pdu_type {
+            // The client's capabilities; the server's stand as they are
+            PDUTYPE_CONFIRMACTIVEPDU => Ok(()),
+            PDUTYPE_DATAPDU => {
+                reader.skip(8)?;
+                let pdu_type = reader.u8()?;
+                if reader.u8()? & PACKET_COMPRESSED != 0 {
+                    return Err("compressed PDU");
+                }
+                reader.skip(2)?;
+                self.data_pdu(pdu_type, reader.rest(), context)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn data_pdu(&mut self, pdu_type: u8, body: &[u8], context: &mut Context) -> Result<(), &'static str> {
+        let mut reader = Reader::new(body);
+        match pdu_type {
+            PDUTYPE2_SYNCHRONIZE => {
+                let mut body = 1u16.to_le_bytes().to_vec();
+                body.extend_from_slice(&USER_CHANNEL.to_le_bytes());
+                self.share_data(PDUTYPE2_SYNCHRONIZE, &body);
+            }
+            PDUTYPE2_CONTROL => {
+                let (action, grant, control) = match reader.u16_le()? {
+                    CTRLACTION_COOPERATE => (CTRLACTION_COOPERATE, 0, 0),
+                    CTRLACTION_REQUEST_CONTROL => (CTRLACTION_GRANTED_CONTROL, USER_CHANNEL, SHARE_ID & 0xFFFF),
+                    _ => return Ok(()),
+                };
+                let mut body = action.to_le_bytes().to_vec();
+                body.extend_from_slice(&grant.to_le_bytes());
+                body.extend_from_slice(&control.to_le_bytes());
+                self.share_data(PDUTYPE2_CONTROL, &body);
+            }
+            PDUTYPE2_FONTLIST => {
+                // No fonts, all of the list at once, four bytes an entry
+                let mut body = Vec::new();
+                for value in [0u16, 0, 0x0003, 4] {
+                    body.extend_from_slice(&value.to_le_bytes());
+                }
+                self.share_data(PDUTYPE2_FONTMAP, &body);
+                if !matches!(self.phase, Phase::Running) {
+                    crate::pr_info!("rdp: {} connected as {}", self.address, self.user.as_deref().unwrap_or("?"));
+                }
+                self.phase = Phase::Running;
+                self.last_update = 0;
+            }
+            PDUTYPE2_INPUT if matches!(self.phase, Phase::Running) => {
+                let count = reader.u16_le()?;
+                reader.skip(2)?;
+                for _ in 0..count {
+                    reader.skip(4)?;
+                    let kind = reader.u16_le()?;
+                    let (flags, first, second) = (reader.u16_le()?, reader.u16_le()?, reader.u16_le()?);
+                    match kind {
+                        INPUT_EVENT_SCANCODE => self.scancode(context, flags, first),
+                        INPUT_EVENT_UNICODE => self.unicode(context, flags, first),
+                        INPUT_EVENT_MOUSE => self.pointer(context, flags, first, second),
+                        _ => {}
+                    }
+                }
+            }
+            PDUTYPE2_SHUTDOWN_REQUEST => {
+                mcs::disconnect(&mut self.output);
+                self.phase = Phase::Closing("client logged off");
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn scancode(&mut self, context: &mut Context, flags: u16, scancode: u16) {
+        let down = flags & KBDFLAGS_RELEASE == 0;
+        let Some(code) = scancode::key_code(scancode, flags & KBDFLAGS_EXTENDED != 0) else { return };
+        input::key(context.keyboard, code, down);
+        input::sync(context.keyboard);
+        let Some(keysym) = keysym::from_key_code(code, self.shift) else { return };
+        match keysym {
+            keysym::SHIFT_L | keysym::SHIFT_R => self.shift = down,
+            keysym::CONTROL_L | keysym::CONTROL_R => self.control = down,
+            _ if down => {
+                if let Some(sequence) = keysym::terminal(keysym, self.control) {
+                    context.typed.push_str(&sequence);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn unicode(&mut self, context: &mut Context, flags: u16, unit: u16) {
+        let down = flags & KBDFLAGS_RELEASE == 0;
+        // Latin-1 keysyms are the characters themselves
+        if let Some(code) = keysym::key_code(unit as u32) {
+            input::key(context.keyboard, code, down);
+            input::sync(context.keyboard);
+        }
+        if !down {
+            return;
+        }
+        match keysym::terminal(unit as u32, self.control) {
+            Some(sequence) => context.typed.push_str(&sequence),
+            None => context.typed.extend(char::from_u32(unit as u32)),
+        }
+    }
+
+    fn pointer(&mut self, context: &mut Context, flags: u16, x: u16, y: u16) {
+        let device = context.pointer;
+        input::report(device, EV_ABS, ABS_X, x as i32);
+        input::report(device, EV_ABS, ABS_Y, y as i32);
+        if flags & PTRFLAGS_WHEEL != 0 {
+            let step = if flags & PTRFLAGS_WHEEL_NEGATIVE != 0 { -1 } else { 1 };
+            input::report(device, EV_REL, REL_WHEEL, step);
+        } else {
+            let buttons = [(PTRFLAGS_BUTTON1, BTN_LEFT), (PTRFLAGS_BUTTON2, BTN_RIGHT), (PTRFLAGS_BUTTON3, BTN_MIDDLE)];
+            for (flag, code) in buttons {
+                if flags & flag != 0 {
+                    input::key(device, code, flags & PTRFLAGS_DOWN != 0);
+                }
+            }
+        }
+        input::sync(device);
+    }
+
+    // Send what changed since the last update
+    fn update(&mut self, context: &mut Context) {
+        let screen = context.screen.get_or_insert_with(screen::capture);
+        if (screen.width, screen.height) != self.size {
+            // The client takes a new size by being deactivated and
+            // activated again
+            let mut body = SHARE_ID.to_le_bytes().to_vec();
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.push(0);
+            self.share_control(PDUTYPE_DEACTIVATEALLPDU, &body);
+            self.demand_active(context);
+            return;
+        }
+        let mut shadow = self.shadow.take().unwrap_or_else(|| screen.unseen());
+        let all = Region { x: 0, y: 0, width: screen.width, height: screen.height };
+        let mut rectangles = Vec::new();
+        let mut count = 0u16;
+        for region in shadow.changes(screen, all) {
+            for piece in bitmap::pieces(region) {
+                let mut rectangle = Vec::new();
+                bitmap::rectangle(&mut rectangle, screen, piece, self.bpp);
+                if rectangles.len() + rectangle.len() > MAX_UPDATE {
+                    self.bitmap_update(count, &rectangles);
+                    rectangles.clear();
+                    count = 0;
+                }
+                rectangles.extend_from_slice(&rectangle);
+                count += 1;
+            }
+            shadow.copy_from(screen, region);
+        }
+        if count > 0 {
+            self.bitmap_update(count, &rectangles);
+            self.last_update = now_ns();
+        }
+        self.shadow = Some(shadow);
+    }
+
+    fn bitmap_update(&mut self, count: u16, rectangles: &[u8]) {
+        let mut body = Vec::with_capacity(4 + rectangles.len());
+        body.extend_from_slice(&UPDATETYPE_BITMAP.to_le_bytes());
+        body.extend_from_slice(&count.to_le_bytes());
+        body.extend_from_slice(rectangles);
+        self.share_data(PDUTYPE2_UPDATE, &body);
+    }
+}
+
+// General, bitmap, order, pointer, input, virtual channel, share and font
+fn capability_sets(bpp: u16, (width, height): (usize, usize)) -> (u16, Vec<u8>) {
+    let mut sets = Vec::new();
+    let mut set = |kind: u16, fields: &[u16], tail: &[u8]| {
+        sets.extend_from_slice(&kind.to_le_bytes());
+        sets.extend_from_slice(&((4 + 2 * fields.len() + tail.len()) as u16).to_le_bytes());
+        fields.iter().for_each(|field| sets.extend_from_slice(&field.to_le_bytes()));
+        sets.extend_from_slice(tail);
+    };
+    // Windows NT, protocol 2, no compression; no fast-path or refresh
+    let extra = LONG_CREDENTIALS_SUPPORTED | NO_BITMAP_COMPRESSION_HDR;
+    set(CAPSTYPE_GENERAL, &[1, 3, 0x0200, 0, 0, extra, 0, 0, 0, 0], &[]);
+    // All depths taken, the desktop's size, resizing and compression allowed,
+    // several rectangles to an update
+    set(CAPSTYPE_BITMAP, &[bpp, 1, 1, 1, width as u16, height as u16, 0, 1, 1, 0, 1, 0], &[]);
+    // No drawing orders
+    let mut order = [0u8; 84];
+    order[20..22].copy_from_slice(&1u16.to_le_bytes());
+    order[22..24].copy_from_slice(&20u16.to_le_bytes());
+    order[26..28].copy_from_slice(&1u16.to_le_bytes());
+    order[30..32].copy_from_slice(&(NEGOTIATEORDERSUPPORT | ZEROBOUNDSDELTASSUPPORT).to_le_bytes());
+    set(CAPSTYPE_ORDER, &[], &order);
+    // Color pointers, 25 cached
+    set(CAPSTYPE_POINTER, &[1, 25, 25], &[]);
+    // Scancodes and Unicode characters, slow-path only
+    set(CAPSTYPE_INPUT, &[INPUT_FLAG_SCANCODES | INPUT_FLAG_UNICODE, 0], &[0; 80]);
+    set(CAPSTYPE_VIRTUALCHANNEL, &[0, 0], &[]);
+    set(CAPSTYPE_SHARE, &[USER_CHANNEL, 0], &[]);
+    set(CAPSTYPE_FONT, &[FONTSUPPORT_FONTLIST, 0], &[]);
+    (8, sets)
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            accounts::logoff(token);
+        }
+        let _ = tcp::close(self.conn);
+    }
+}
@@ -0,0 +1,335 @@
+// The layers under RDP's own PDUs
+//
+// TPKT frames X.224 TPDUs. The connection request and confirm settle the
+// security protocol, and data TPDUs carry MCS. MCS's connect PDUs are BER
+// and hold GCC's conference create PDUs, in PER, with the client's and
+// server's data blocks; its domain PDUs, also PER, attach the user, join
+// channels and carry what is sent on them.
+
+use alloc::vec::Vec;
+
+/// The one user there is, and its channel
+pub const USER_CHANNEL: u16 = 1002;
+/// The channel RDP's own PDUs go on
+pub const IO_CHANNEL: u16 = 1003;
+/// Static virtual channels are numbered from here
+pub const FIRST_STATIC_CHANNEL: u16 = 1004;
+
+// Standard RDP security, rather than TLS or CredSSP
+const PROTOCOL_RDP: u32 = 0;
+
+// Domain PDU choices
+const ERECT_DOMAIN_REQUEST: u8 = 1;
+const DISCONNECT_PROVIDER_ULTIMATUM: u8 = 8;
+const ATTACH_USER_REQUEST: u8 = 10;
+const ATTACH_USER_CONFIRM: u8 = 11;
+const CHANNEL_JOIN_REQUEST: u8 = 14;
+const CHANNEL_JOIN_CONFIRM: u8 = 15;
+const SEND_DATA_REQUEST: u8 = 25;
+const SEND_DATA_INDICATION: u8 = 26;
+
+// Channel IDs in domain PDUs count from here
+const CHANNEL_BASE: u16 = 1001;
+
+// maxChannelIds 34, maxUserIds 3, maxTokenIds 0, numPriorities 1,
+// minThroughput 0, maxHeight 1, maxMCSPDUsize 65528, protocolVersion 2
+const DOMAIN_PARAMETERS: [u8; 26] = [
+    0x02, 0x01, 0x22, 0x02, 0x01, 0x03, 0x02, 0x01, 0x00, 0x02, 0x01, 0x01, 0x02, 0x01, 0x00, 0x02, 0x01, 0x01,
+    0x02, 0x03, 0x00, 0xFF, 0xF8, 0x02, 0x01, 0x02,
+];
+
+/// Most user data a send data PDU carries, the largest a PER length
+/// holds unfragmented
+pub const MAX_SEND_DATA: usize = 0x3FFF;
+
+const TRUNCATED: &str = "PDU truncated";
+
+/// Reads fields from a PDU, failing at its end
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, at: 0 }
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self.bytes.get(self.at..self.at.saturating_add(length)).ok_or(TRUNCATED)?;
+        self.at += length;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, length: usize) -> Result<(), &'static str> {
+        self.take(length).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16_le(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u16_be(&mut self) -> Result<u16, &'static str> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32_le(&mut self) -> Result<u32, &'static str> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// What is left
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.at..];
+        self.at = self.bytes.len();
+        rest
+    }
+}
+
+/// The length of the TPKT frame at the start of `input`, once all of it
+/// has arrived
+pub fn frame_length(input: &[u8]) -> Result<Option<usize>, &'static str> {
+    let Some(header) = input.get(..4) else { return Ok(None) };
+    // Fast-path PDUs start otherwise; the server does not ask for them
+    if header[0] != 3 {
+        return Err("not a TPKT frame");
+    }
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if length < 7 {
+        return Err("TPKT frame too short");
+    }
+    Ok((input.len() >= length).then_some(length))
+}
+
+pub enum Tpdu<'a> {
+    /// A connection request, and what follows its fixed part
+    ConnectionRequest(&'a [u8]),
+    Data(&'a [u8]),
+    Disconnect,
+}
+
+pub fn tpdu(frame: &[u8]) -> Result<Tpdu<'_>, &'static str> {
+    let indicator = frame[4] as usize;
+    match frame[5] & 0xF0 {
+        0xE0 => Ok(Tpdu::ConnectionRequest(frame.get(11..5 + indicator).ok_or(TRUNCATED)?)),
+        0xF0 => Ok(Tpdu::Data(&frame[7..])),
+        0x80 => Ok(Tpdu::Disconnect),
+        _ => Err("unknown X.224 TPDU"),
+    }
+}
+
+/// The protocols a connection request asks for, if it negotiates
+pub fn requested_protocols(request: &[u8]) -> Option<u32> {
+    // A cookie or routing token comes first, ended by CR LF
+    let negotiation = match request.windows(2).position(|pair| pair == b"\r\n") {
+        Some(end) if request.starts_with(b"Cookie:") => &request[end + 2..],
+        _ => request,
+    };
+    match negotiation {
+        [1, _, 8, 0, protocols @ ..] if protocols.len() >= 4 => {
+            Some(u32::from_le_bytes([protocols[0], protocols[1], protocols[2], protocols[3]]))
+        }
+        _ => None,
+    }
+}
+
+fn tpkt(out: &mut Vec<u8>, length: usize) {
+    out.extend_from_slice(&[3, 0]);
+    out.extend_from_slice(&((length + 4) as u16).to_be_bytes());
+}
+
+/// Confirm a connection, choosing standard RDP security if the client
+/// negotiated
+pub fn connection_confirm(out: &mut Vec<u8>, negotiated: bool) {
+    let indicator = if negotiated { 14 } else { 6 };
+    tpkt(out, indicator + 1);
+    out.extend_from_slice(&[indicator as u8, 0xD0, 0x00, 0x00, 0x12, 0x34, 0x00]);
+    if negotiated {
+        out.extend_from_slice(&[2, 0, 8, 0]);
+        out.extend_from_slice(&PROTOCOL_RDP.to_le_bytes());
+    }
+}
+
+/// An MCS PDU in a data TPDU
+pub fn data(out: &mut Vec<u8>, pdu: &[u8]) {
+    tpkt(out, 3 + pdu.len());
+    out.extend_from_slice(&[2, 0xF0, 0x80]);
+    out.extend_from_slice(pdu);
+}
+
+fn ber_length(reader: &mut Reader) -> Result<usize, &'static str> {
+    match reader.u8()? {
+        length @ 0..=0x7F => Ok(length as usize),
+        0x81 => Ok(reader.u8()? as usize),
+        0x82 => Ok(reader.u16_be()? as usize),
+        _ => Err("BER length too long"),
+    }
+}
+
+fn ber_field<'a>(reader: &mut Reader<'a>, tag: u8) -> Result<&'a [u8], &'static str> {
+    if reader.u8()? != tag {
+        return Err("unexpected BER tag");
+    }
+    let length = ber_length(reader)?;
+    reader.take(length)
+}
+
+fn write_ber_length(out: &mut Vec<u8>, length: usize) {
+    match length {
+        0..=0x7F => out.push(length as u8),
+        0x80..=0xFF => out.extend_from_slice(&[0x81, length as u8]),
+        _ => {
+            out.push(0x82);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+    }
+}
+
+fn write_ber_field(out: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    out.push(tag);
+    write_ber_length(out, contents.len());
+    out.extend_from_slice(contents);
+}
+
+fn per_length(reader: &mut Reader) -> Result<usize, &'static str> {
+    let first = reader.u8()? as usize;
+    if first & 0x80 == 0 {
+        return Ok(first);
+    }
+    Ok((first & 0x7F) << 8 | reader.u8()? as usize)
+}
+
+fn write_per_length(out: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        out.push(length as u8);
+    } else {
+        out.extend_from_slice(&(0x8000 | length as u16).to_be_bytes());
+    }
+}
+
+/// The client data blocks of an MCS Connect Initial
+pub fn connect_initial(pdu: &[u8]) -> Result<&[u8], &'static str> {
+    let mut reader = Reader::new(pdu);
+    if reader.take(2)? != [0x7F, 0x65] {
+        return Err("not an MCS Connect Initial");
+    }
+    ber_length(&mut reader)?;
+    // Domain selectors, the upward flag and three sets of domain parameters
+    for tag in [0x04, 0x04, 0x01, 0x30, 0x30, 0x30] {
+        ber_field(&mut reader, tag)?;
+    }
+    // GCC's Conference Create Request, whose data blocks follow the
+    // client's H.221 key and their length
+    let user_data = ber_field(&mut reader, 0x04)?;
+    let key = user_data.windows(4).position(|key| key == b"Duca").ok_or("no client data")?;
+    let mut reader = Reader::new(&user_data[key + 4..]);
+    let length = per_length(&mut reader)?;
+    reader.take(length)
+}
+
+/// GCC data blocks: each one's type and what follows its header
+pub fn blocks(data: &[u8]) -> Result<Vec<(u16, &[u8])>, &'static str> {
+    let mut reader = Reader::new(data);
+    let mut blocks = Vec::new();
+    while let Ok(kind) = reader.u16_le() {
+        let length = (reader.u16_le()? as usize).checked_sub(4).ok_or("bad data block length")?;
+        blocks.push((kind, reader.take(length)?));
+    }
+    Ok(blocks)
+}
+
+pub fn block(out: &mut Vec<u8>, kind: u16, contents: &[u8]) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&((4 + contents.len()) as u16).to_le_bytes());
+    out.extend_from_slice(contents);
+}
+
+/// An MCS Connect Response carrying the server's data blocks
+pub fn connect_response(out: &mut Vec<u8>, blocks: &[u8]) {
+    // GCC's Conference Create Response: node 1001, tag 1, success, and
+    // the data blocks after the server's H.221 key
+    let mut response = alloc::vec![0x14, 0x76, 0x0A, 0x01, 0x01, 0x00, 0x01, 0xC0, 0x00];
+    response.extend_from_slice(b"McDn");
+    write_per_length(&mut response, blocks.len());
+    response.extend_from_slice(blocks);
+    let mut gcc = alloc::vec![0x00, 0x05, 0x00, 0x14, 0x7C, 0x00, 0x01];
+    write_per_length(&mut gcc, response.len());
+    gcc.extend_from_slice(&response);
+
+    // Success, connect ID 0, the domain parameters and the GCC PDU
+    let mut body = alloc::vec![0x0A, 0x01, 0x00, 0x02, 0x01, 0x00];
+    write_ber_field(&mut body, 0x30, &DOMAIN_PARAMETERS);
+    write_ber_field(&mut body, 0x04, &gcc);
+    let mut pdu = alloc::vec![0x7F, 0x66];
+    write_ber_length(&mut pdu, body.len());
+    pdu.extend_from_slice(&body);
+    data(out, &pdu);
+}
+
+pub enum Domain<'a> {
+    ErectDomain,
+    AttachUser,
+    ChannelJoin(u16),
+    SendData { channel: u16, data: &'a [u8] },
+    Disconnect,
+}
+
+pub fn domain_pdu(pdu: &[u8]) -> Result<Domain<'_>, &'static str> {
+    let mut reader = Reader::new(pdu);
+    match reader.u8()? >> 2 {
+        ERECT_DOMAIN_REQUEST => Ok(Domain::ErectDomain),
+        ATTACH_USER_REQUEST => Ok(Domain::AttachUser),
+        CHANNEL_JOIN_REQUEST => {
+            reader.skip(2)?;
+            Ok(Domain::ChannelJoin(reader.u16_be()?))
+        }
+        SEND_DATA_REQUEST => {
+            // Initiator, then the channel, then priority and segmentation
+            reader.skip(2)?;
+            let channel = reader.u16_be()?;
+            reader.skip(1)?;
+            let length = per_length(&mut reader)?;
+            Ok(Domain::SendData { channel, data: reader.take(length)? })
+        }
+        DISCONNECT_PROVIDER_ULTIMATUM => Ok(Domain::Disconnect),
+        _ => Err("unknown MCS domain PDU"),
+    }
+}
+
+pub fn attach_user_confirm(out: &mut Vec<u8>) {
+    let mut pdu = alloc::vec![ATTACH_USER_CONFIRM << 2 | 2, 0];
+    pdu.extend_from_slice(&(USER_CHANNEL - CHANNEL_BASE).to_be_bytes());
+    data(out, &pdu);
+}
+
+pub fn channel_join_confirm(out: &mut Vec<u8>, channel: u16) {
+    let mut pdu = alloc::vec![CHANNEL_JOIN_CONFIRM << 2 | 2, 0];
+    pdu.extend_from_slice(&(USER_CHANNEL - CHANNEL_BASE).to_be_bytes());
+    pdu.extend_from_slice(&channel.to_be_bytes());
+    pdu.extend_from_slice(&channel.to_be_bytes());
+    data(out, &pdu);
+}
+
+/// Send `payload` on a channel; it must be no longer than MAX_SEND_DATA
+pub fn send_data_indication(out: &mut Vec<u8>, channel: u16, payload: &[u8]) {
+    let mut pdu = Vec::with_capacity(8 + payload.len());
+    pdu.push(SEND_DATA_INDICATION << 2);
+    pdu.extend_from_slice(&(USER_CHANNEL - CHANNEL_BASE).to_be_bytes());
+    pdu.extend_from_slice(&channel.to_be_bytes());
+    // High priority, the whole of it in one segment
+    pdu.push(0x70);
+    write_per_length(&mut pdu, payload.len());
+    pdu.extend_from_slice(payload);
+    data(out, &pdu);
+}
+
+/// Tell the client the server is ending the connection
+pub fn disconnect(out: &mut Vec<u8>) {
+    data(out, &[DISCONNECT_PROVIDER_ULTIMATUM << 2, 0x80]);
+}
@@ -0,0 +1,184 @@
+//! RDP server
+//!
+//! Remote desktop access for Windows' own client and the others that
+//! speak RDP. The server shows the same screen VNC does, as uncompressed
+//! bitmaps of what changed, and takes keys by scancode or character and
+//! the pointer. Connections use RDP's standard security: RC4 keyed by a
+//! random each side contributes, the server's key sent in a certificate
+//! signed the way Windows expects. There is no TLS or NLA.
+//!
+//! Clients log on with the user name and password of an administrator;
+//! anyone else is turned away. It runs from the main loop, a poll at a
+//! time.
+
+pub mod bitmap;
+pub mod client;
+pub mod mcs;
+pub mod rc4;
+pub mod scancode;
+pub mod security;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+use crate::input::{self, DeviceKind};
+use crate::net::tcp;
+use crate::time::clocksource::now_ns;
+use client::{Client, Context};
+use security::ServerKey;
+
+pub const DEFAULT_PORT: u16 = 3389;
+// Clients served at once; more are turned away
+const MAX_CLIENTS: usize = 4;
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+static RDP: Param<bool> = Param::new("rdp", false, "Start the RDP server at boot");
+static PORT: Param<i64> = Param::new("rdp.port", DEFAULT_PORT as i64, "Port the RDP server listens on");
+
+struct Server {
+    port: u16,
+    key: ServerKey,
+    keyboard: usize,
+    pointer: usize,
+    clients: Vec<Client>,
+    next_id: u32,
+}
+
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+/// A client, as `status` lists it
+pub struct ClientInfo {
+    pub id: u32,
+    pub address: String,
+    pub connected_ns: u64,
+    /// The account it logged on as
+    pub user: Option<String>,
+    /// The name of the client computer
+    pub name: String,
+    pub bpp: u16,
+    /// Session key length in bits
+    pub encryption: Option<u32>,
+    pub bytes_sent: u64,
+}
+
+pub struct Status {
+    pub port: u16,
+    pub clients: Vec<ClientInfo>,
+}
+
+/// Start the server if the command line asks for it
+pub fn init() {
+    if !RDP.get() {
+        return;
+    }
+    let port = PORT.get();
+    let Ok(port) = u16::try_from(port) else {
+        crate::pr_warn!("rdp: {} is not a port", port);
+        return;
+    };
+    match start(port) {
+        Ok(()) => crate::pr_info!("rdp: listening on port {}", port),
+        Err(e) => crate::pr_warn!("rdp: {}", e),
+    }
+}
+
+pub fn start(port: u16) -> Result<(), &'static str> {
+    let mut server = SERVER.lock();
+    if server.is_some() {
+        return Err("already running");
+    }
+    let key = ServerKey::generate()?;
+    tcp::listen(port)?;
+    *server = Some(Server {
+        port,
+        key,
+        keyboard: input::register("RDP keyboard", DeviceKind::Keys),
+        pointer: input::register("RDP pointer", DeviceKind::Mouse),
+        clients: Vec::new(),
+        next_id: 1,
+    });
+    Ok(())
+}
+
+/// Stop listening and drop every client
+pub fn stop() -> Result<(), &'static str> {
+    let server = SERVER.lock().take().ok_or("not running")?;
+    tcp::unlisten(server.port);
+    input::unregister(server.keyboard);
+    input::unregister(server.pointer);
+    Ok(())
+}
+
+pub fn status() -> Option<Status> {
+    let server = SERVER.lock();
+    let server = server.as_ref()?;
+    let clients = server
+        .clients
+        .iter()
+        .map(|client| ClientInfo {
+            id: client.id,
+            address: client.address.clone(),
+            connected_ns: client.connected_at,
+            user: client.user.clone(),
+            name: client.name.clone(),
+            bpp: client.bpp,
+            encryption: client.encryption(),
+            bytes_sent: client.bytes_sent,
+        })
+        .collect();
+    Some(Status { port: server.port, clients })
+}
+
+pub fn disconnect(id: u32) -> Result<(), &'static str> {
+    let mut server = SERVER.lock();
+    let server = server.as_mut().ok_or("not running")?;
+    let index = server.clients.iter().position(|client| client.id == id).ok_or("no such client")?;
+    let client = server.clients.remove(index);
+    crate::pr_info!("rdp: {} disconnected", client.address);
+    Ok(())
+}
+
+/// Take new connections and serve the clients; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+
+    let mut typed = String::new();
+    {
+        let mut server = SERVER.lock();
+        let Some(server) = server.as_mut() else { return };
+        while let Some(conn) = tcp::accept(server.port) {
+            if server.clients.len() >= MAX_CLIENTS {
+                let _ = tcp::close(conn);
+                continue;
+            }
+            server.clients.push(Client::new(server.next_id, conn));
+            server.next_id += 1;
+        }
+
+        let mut screen = None;
+        let mut context = Context {
+            key: &server.key,
+            keyboard: server.keyboard,
+            pointer: server.pointer,
+            screen: &mut screen,
+            typed: &mut typed,
+        };
+        server.clients.retain_mut(|client| match client.poll(&mut context) {
+            Ok(()) => true,
+            Err(reason) => {
+                crate::pr_info!("rdp: {} disconnected: {}", client.address, reason);
+                false
+            }
+        });
+    }
+    // As for VNC, the shell hears the keys once the server is unlocked
+    typed.chars().for_each(crate::cmd_shell::handle_keyboard_input);
+}
@@ -0,0 +1,34 @@
+// RDP keyboard scancodes
+//
+// Scancode set 1, whose numbering Linux key codes follow, with the keys
+// that send an E0 prefix flagged as extended instead.
+
+// Extended scancodes and the key codes of their keys
+const EXTENDED: [(u16, u16); 18] = [
+    (0x1C, 96),  // keypad Enter
+    (0x1D, 97),  // right Ctrl
+    (0x35, 98),  // keypad /
+    (0x37, 99),  // Print Screen
+    (0x38, 100), // right Alt
+    (0x47, 102), // Home
+    (0x48, 103), // Up
+    (0x49, 104), // Page Up
+    (0x4B, 105), // Left
+    (0x4D, 106), // Right
+    (0x4F, 107), // End
+    (0x50, 108), // Down
+    (0x51, 109), // Page Down
+    (0x52, 110), // Insert
+    (0x53, 111), // Delete
+    (0x5B, 125), // left Windows
+    (0x5C, 126), // right Windows
+    (0x5D, 127), // Menu
+];
+
+/// The Linux key code of the key a scancode comes from
+pub fn key_code(scancode: u16, extended: bool) -> Option<u16> {
+    if extended {
+        return EXTENDED.iter().find(|&&(extended, _)| extended == scancode).map(|&(_, code)| code);
+    }
+    matches!(scancode, 0x01..=0x58).then_some(scancode)
+}
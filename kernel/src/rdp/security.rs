@@ -0,0 +1,243 @@
+// RDP standard security
+//
+// The server's RSA key goes to the client in a proprietary certificate,
+// signed with the well-known Terminal Services key, and the client
+// encrypts its random with it. Both randoms make the session keys: an
+// RC4 stream each way, a MAC key, and new keys every 4096 packets.
+// Numbers on the wire are little-endian.
+
+use alloc::vec::Vec;
+
+use crate::crypto::asymmetric::{self, AsymmetricCrypto, RsaPublicKey, RSA};
+use crate::crypto::{md5, sha1};
+
+use super::rc4::Rc4;
+
+pub const ENCRYPTION_40BIT: u32 = 0x01;
+pub const ENCRYPTION_128BIT: u32 = 0x02;
+pub const ENCRYPTION_56BIT: u32 = 0x08;
+
+pub const RANDOM_LENGTH: usize = 32;
+const KEY_BITS: usize = 512;
+const MODULUS_LENGTH: usize = KEY_BITS / 8;
+// Keys are changed after this many packets
+const KEY_UPDATE_INTERVAL: u32 = 4096;
+
+const PAD1: [u8; 40] = [0x36; 40];
+const PAD2: [u8; 48] = [0x5C; 48];
+
+const CERT_CHAIN_VERSION_1: u32 = 1;
+const SIGNATURE_ALG_RSA: u32 = 1;
+const KEY_EXCHANGE_ALG_RSA: u32 = 1;
+const BB_RSA_KEY_BLOB: u16 = 6;
+const BB_RSA_SIGNATURE_BLOB: u16 = 8;
+const RSA1_MAGIC: u32 = 0x3141_5352;
+
+// The Terminal Services signing key, published in MS-RDPBCGR 5.3.3.1.1
+const TS_MODULUS: [u8; 64] = [
+    0x3D, 0x3A, 0x5E, 0xBD, 0x72, 0x43, 0x3E, 0xC9, 0x4D, 0xBB, 0xC1, 0x1E, 0x4A, 0xBA, 0x5F, 0xCB, 0x3E, 0x88,
+    0x20, 0x87, 0xEF, 0xF5, 0xC1, 0xE2, 0xD7, 0xB7, 0x6B, 0x9A, 0xF2, 0x52, 0x45, 0x95, 0xCE, 0x63, 0x65, 0x6B,
+    0x58, 0x3A, 0xFE, 0xEF, 0x7C, 0xE7, 0xBF, 0xFE, 0x3D, 0xF6, 0x5C, 0x7D, 0x6C, 0x5E, 0x06, 0x09, 0x1A, 0xF5,
+    0x61, 0xBB, 0x20, 0x93, 0x09, 0x5F, 0x05, 0x6D, 0xEA, 0x87,
+];
+const TS_EXPONENT: [u8; 4] = [0x5B, 0x7B, 0x88, 0xC0];
+const TS_PRIVATE_EXPONENT: [u8; 64] = [
+    0x87, 0xA7, 0x19, 0x32, 0xDA, 0x11, 0x87, 0x55, 0x58, 0x00, 0x16, 0x16, 0x25, 0x65, 0x68, 0xF8, 0x24, 0x3E,
+    0xE6, 0xFA, 0xE9, 0x67, 0x49, 0x94, 0xCF, 0x92, 0xCC, 0x33, 0x99, 0xE8, 0x08, 0x60, 0x17, 0x9A, 0x12, 0x9F,
+    0x24, 0xDD, 0xB1, 0x24, 0x99, 0xC7, 0x3A, 0xB8, 0x0A, 0x7B, 0x0D, 0xDD, 0x35, 0x07, 0x79, 0x17, 0x0B, 0x51,
+    0x9B, 0xB3, 0xC7, 0x10, 0x01, 0x13, 0xE7, 0x3F, 0xF3, 0x5F,
+];
+
+fn reversed(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().rev().copied().collect()
+}
+
+/// The server's key pair, made when the server starts, and its certificate
+pub struct ServerKey {
+    private: Vec<u8>,
+    certificate: Vec<u8>,
+}
+
+impl ServerKey {
+    pub fn generate() -> Result<ServerKey, &'static str> {
+        let (public, private) = RSA::new(KEY_BITS).generate_keypair().map_err(|_| "key generation failed")?;
+        let public = RsaPublicKey::parse(&public).map_err(|_| "key generation failed")?;
+        let exponent = public.exponent().iter().fold(0u32, |value, &byte| value << 8 | byte as u32);
+        let certificate = certificate(&reversed(&public.modulus()), exponent)?;
+        Ok(ServerKey { private, certificate })
+    }
+
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// The client random, from the encrypted one a Security Exchange PDU
+    /// carries
+    pub fn client_random(&self, encrypted: &[u8]) -> Result<[u8; RANDOM_LENGTH], &'static str> {
+        // Padded with eight zero bytes, which the modulus does not cover
+        let encrypted = encrypted.get(..MODULUS_LENGTH).ok_or("client random too short")?;
+        let decrypted = asymmetric::rsa_private_raw(&self.private, &reversed(encrypted))
+            .map_err(|_| "client random not encrypted with the server's key")?;
+        let mut random = [0u8; RANDOM_LENGTH];
+        random.copy_from_slice(&reversed(&decrypted)[..RANDOM_LENGTH]);
+        Ok(random)
+    }
+}
+
+fn certificate(modulus: &[u8], exponent: u32) -> Result<Vec<u8>, &'static str> {
+    let mut certificate = Vec::new();
+    for value in [CERT_CHAIN_VERSION_1, SIGNATURE_ALG_RSA, KEY_EXCHANGE_ALG_RSA] {
+        certificate.extend_from_slice(&value.to_le_bytes());
+    }
+    // The public key, as an RSA1 blob with the modulus padded by eight
+    // zero bytes
+    let key_length = modulus.len() + 8;
+    certificate.extend_from_slice(&BB_RSA_KEY_BLOB.to_le_bytes());
+    certificate.extend_from_slice(&((20 + key_length) as u16).to_le_bytes());
+    let bits = modulus.len() * 8;
+    for value in [RSA1_MAGIC, key_length as u32, bits as u32, (bits / 8 - 1) as u32, exponent] {
+        certificate.extend_from_slice(&value.to_le_bytes());
+    }
+    certificate.extend_from_slice(modulus);
+    certificate.extend_from_slice(&[0; 8]);
+
+    // Signed over all of that: its MD5, padded to 63 bytes, with the
+    // Terminal Services key
+    let mut padded = [0u8; 64];
+    padded[..16].copy_from_slice(&md5(&certificate));
+    padded[17..62].fill(0xFF);
+    padded[62] = 0x01;
+    let key =
+        asymmetric::rsa_private_key(&reversed(&TS_MODULUS), &reversed(&TS_EXPONENT), &reversed(&TS_PRIVATE_EXPONENT));
+    let signature = asymmetric::rsa_private_raw(&key, &reversed(&padded)).map_err(|_| "certificate signing failed")?;
+    certificate.extend_from_slice(&BB_RSA_SIGNATURE_BLOB.to_le_bytes());
+    certificate.extend_from_slice(&((signature.len() + 8) as u16).to_le_bytes());
+    certificate.extend_from_slice(&reversed(&signature));
+    certificate.extend_from_slice(&[0; 8]);
+    Ok(certificate)
+}
+
+// MD5 of a secret and the SHA-1 of a salt, the secret and both randoms
+fn salted_hash(secret: &[u8], salt: &[u8], client: &[u8], server: &[u8]) -> [u8; 16] {
+    let sha = sha1(&[salt, secret, client, server].concat());
+    md5(&[secret, &sha].concat())
+}
+
+// 40- and 56-bit keys are the first 64 bits of the 128-bit ones with
+// their top bytes replaced
+fn reduce(method: u32, key: &mut Vec<u8>) {
+    match method {
+        ENCRYPTION_40BIT => {
+            key.truncate(8);
+            key[..3].copy_from_slice(&[0xD1, 0x26, 0x9E]);
+        }
+        ENCRYPTION_56BIT => {
+            key.truncate(8);
+            key[0] = 0xD1;
+        }
+        _ => {}
+    }
+}
+
+// One direction's RC4 stream, and the keys it was and is using
+struct Stream {
+    rc4: Rc4,
+    initial: Vec<u8>,
+    key: Vec<u8>,
+    uses: u32,
+    // Packets ever, for salted MACs
+    count: u32,
+}
+
+impl Stream {
+    fn new(key: Vec<u8>) -> Stream {
+        Stream { rc4: Rc4::new(&key), initial: key.clone(), key, uses: 0, count: 0 }
+    }
+
+    fn apply(&mut self, method: u32, data: &mut [u8]) {
+        if self.uses == KEY_UPDATE_INTERVAL {
+            let sha = sha1(&[&self.initial[..], &PAD1, &self.key].concat());
+            let digest = md5(&[&self.initial[..], &PAD2, &sha].concat());
+            let mut key = digest[..self.key.len()].to_vec();
+            Rc4::new(&key.clone()).apply(&mut key);
+            reduce(method, &mut key);
+            self.rc4 = Rc4::new(&key);
+            self.key = key;
+            self.uses = 0;
+        }
+        self.rc4.apply(data);
+        self.uses += 1;
+        self.count = self.count.wrapping_add(1);
+    }
+}
+
+/// A connection's session keys
+pub struct Session {
+    method: u32,
+    mac_key: Vec<u8>,
+    encrypt: Stream,
+    decrypt: Stream,
+}
+
+impl Session {
+    pub fn new(method: u32, client: &[u8; RANDOM_LENGTH], server: &[u8; RANDOM_LENGTH]) -> Session {
+        let pre_master = [&client[..24], &server[..24]].concat();
+        let master: Vec<u8> =
+            [&b"A"[..], b"BB", b"CCC"].iter().flat_map(|salt| salted_hash(&pre_master, salt, client, server)).collect();
+        let blob: Vec<u8> =
+            [&b"X"[..], b"YY", b"ZZZ"].iter().flat_map(|salt| salted_hash(&master, salt, client, server)).collect();
+        let final_hash = |key: &[u8]| {
+            let mut key = md5(&[key, client, server].concat()).to_vec();
+            reduce(method, &mut key);
+            key
+        };
+        let mut mac_key = blob[..16].to_vec();
+        reduce(method, &mut mac_key);
+        // The client decrypts with the second key and encrypts with the third
+        Session {
+            method,
+            mac_key,
+            encrypt: Stream::new(final_hash(&blob[16..32])),
+            decrypt: Stream::new(final_hash(&blob[32..48])),
+        }
+    }
+
+    /// Key length in bits
+    pub fn bits(&self) -> u32 {
+        match self.method {
+            ENCRYPTION_40BIT => 40,
+            ENCRYPTION_56BIT => 56,
+            _ => 128,
+        }
+    }
+
+    fn mac(&self, data: &[u8], count: Option<u32>) -> [u8; 8] {
+        let mut inner = [&self.mac_key[..], &PAD1, &(data.len() as u32).to_le_bytes(), data].concat();
+        if let Some(count) = count {
+            inner.extend_from_slice(&count.to_le_bytes());
+        }
+        let sha = sha1(&inner);
+        let digest = md5(&[&self.mac_key[..], &PAD2, &sha].concat());
+        let mut signature = [0u8; 8];
+        signature.copy_from_slice(&digest[..8]);
+        signature
+    }
+
+    /// Encrypt a packet in place, returning its signature
+    pub fn encrypt(&mut self, data: &mut [u8]) -> [u8; 8] {
+        let signature = self.mac(data, None);
+        self.encrypt.apply(self.method, data);
+        signature
+    }
+
+    /// Decrypt a packet in place and check its signature, salted with the
+    /// packets before it if the client said so
+    pub fn decrypt(&mut self, data: &mut [u8], signature: &[u8], salted: bool) -> Result<(), &'static str> {
+        let count = self.decrypt.count;
+        self.decrypt.apply(self.method, data);
+        if self.mac(data, salted.then_some(count)) != signature {
+            return Err("bad packet signature");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! Acquisition: a scan job from the scanner to files in the VFS
+//!
+//! A SANE device hands over raw frames a strip at a time; once a page is
+//! whole it is developed (gray conversion, brightness, contrast, gamma,
+//! the line-art threshold) and saved as PNM or JPEG, or kept for a PDF
+//! written when the last page is in. An eSCL scanner encodes its pages
+//! itself, so they are saved as they come, except that raw images,
+//! which it sends as PNG, are decoded and developed the same way.
+//!
+//! Files are named `<directory>/scan<job>-<page>.<ext>`, or
+//! `scan<job>.pdf` for a PDF.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::time::clocksource::now_ns;
+
+use super::backend::{FrameFormat, ScanParameters, ScannerBackend};
+use super::escl::{self, Endpoint, Request};
+use super::formats::{self, PdfPage};
+use super::image_processing::ImageProcessor;
+use super::{ImageFormat, ScanMode, ScanSettings};
+
+// How long to wait before asking a busy scanner again
+const RETRY_NS: u64 = 1_000_000_000;
+// Pages with less ink than this are left out when blank detection is on
+const BLANK_THRESHOLD: f32 = 0.002;
+
+/// A page of 8-bit samples, gray or RGB, row after row
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub pixels: Vec<u8>,
+}
+
+enum Source {
+    Local {
+        parameters: ScanParameters,
+        data: Vec<u8>,
+    },
+    Escl {
+        endpoint: Endpoint,
+        // The job's path once the scanner has made it
+        job: Option<String>,
+        request: Option<Request>,
+        retry_at: u64,
+    },
+}
+
+pub struct Acquisition {
+    pub job_id: u32,
+    pub scanner_id: u32,
+    settings: ScanSettings,
+    directory: String,
+    source: Source,
+    pdf_pages: Vec<PdfPage>,
+    pub pages: u32,
+    pub files: Vec<String>,
+}
+
+impl Acquisition {
+    /// A scan on a SANE device, which the backend has started
+    pub fn local(
+        job_id: u32,
+        scanner_id: u32,
+        settings: ScanSettings,
+        directory: &str,
+        backend: &ScannerBackend,
+    ) -> Result<Acquisition, &'static str> {
+        let parameters = backend.get_scan_parameters(scanner_id)?;
+        let source = Source::Local { parameters, data: Vec::new() };
+        Ok(Self::new(job_id, scanner_id, settings, directory, source))
+    }
+
+    /// A scan on an eSCL scanner, starting with the request that makes the job
+    pub fn escl(
+        job_id: u32,
+        scanner_id: u32,
+        settings: ScanSettings,
+        directory: &str,
+        endpoint: Endpoint,
+    ) -> Result<Acquisition, &'static str> {
+        let body = escl::scan_settings(&settings)?;
+        let path = format!("{}/ScanJobs", endpoint.root());
+        let request = Request::new(&endpoint, "POST", &path, Some(("text/xml", body.as_bytes())))?;
+        let source = Source::Escl { endpoint, job: None, request: Some(request), retry_at: 0 };
+        Ok(Self::new(job_id, scanner_id, settings, directory, source))
+    }
+
+    fn new(job_id: u32, scanner_id: u32, settings: ScanSettings, directory: &str, source: Source) -> Acquisition {
+        Acquisition {
+            job_id,
+            scanner_id,
+            settings,
+            directory: String::from(directory.trim_end_matches('/')),
+            source,
+            pdf_pages: Vec::new(),
+            pages: 0,
+            files: Vec::new(),
+        }
+    }
+
+    /// Where a scanner is to be told to drop the job, if it has made one
+    pub fn escl_job(&self) -> Option<(Endpoint, String)> {
+        match &self.source {
+            Source::Escl { endpoint, job: Some(job), .. } => Some((endpoint.clone(), job.clone())),
+            _ => None,
+        }
+    }
+
+    /// Move the scan on; true once every page is saved
+    pub fn step(&mut self, backend: &mut ScannerBackend) -> Result<bool, &'static str> {
+        match &mut self.source {
+            Source::Local { parameters, data } => {
+                let strip = backend.read_scan_data(self.scanner_id)?;
+                if !strip.is_empty() {
+                    data.extend_from_slice(&strip);
+                    return Ok(false);
+                }
+                let frame = Frame {
+                    width: parameters.pixels_per_line as usize,
+                    height: parameters.lines as usize,
+                    channels: if parameters.format == FrameFormat::RGB { 3 } else { 1 },
+                    pixels: core::mem::take(data),
+                };
+                if frame.pixels.len() < frame.width * frame.height * frame.channels {
+                    return Err("scanner sent a short frame");
+                }
+                let more = backend.next_page(self.scanner_id);
+                if more {
+                    *parameters = backend.get_scan_parameters(self.scanner_id)?;
+                }
+                self.add_frame(frame)?;
+                if more {
+                    return Ok(false);
+                }
+                let _ = backend.cancel_scan(self.scanner_id);
+                self.finish()
+            }
+            Source::Escl { endpoint, job, request, retry_at } => {
+                let Some(pending) = request.as_mut() else {
+                    if now_ns() >= *retry_at {
+                        let path = format!("{}/NextDocument", job.as_deref().unwrap_or_default());
+                        *request = Some(Request::new(endpoint, "GET", &path, None)?);
+                    }
+                    return Ok(false);
+                };
+                let Some(response) = pending.poll()? else {
+                    return Ok(false);
+                };
+                *request = None;
+                match (job.is_some(), response.status) {
+                    (false, 201) => {
+                        let location = response.location.ok_or("scanner made the job but gave no URL")?;
+                        *job = Some(escl::job_path(&location));
+                        Ok(false)
+                    }
+                    (_, 503) => {
+                        if job.is_none() {
+                            return Err("scanner is busy");
+                        }
+                        // Still scanning the page
+                        *retry_at = now_ns() + RETRY_NS;
+                        Ok(false)
+                    }
+                    (false, 409) => Err("scanner cannot scan with those settings"),
+                    (false, _) => Err("scanner refused the scan"),
+                    (true, 200) => {
+                        self.add_document(&response.content_type, response.body)?;
+                        Ok(false)
+                    }
+                    // No more pages
+                    (true, 404) if self.pages > 0 => self.finish(),
+                    (true, 404) => Err("scanner had no pages to scan; is the feeder empty?"),
+                    (true, _) => Err("scanner failed while scanning"),
+                }
+            }
+        }
+    }
+
+    fn line_art(&self) -> bool {
+        matches!(self.settings.mode, ScanMode::Lineart | ScanMode::Halftone)
+    }
+
+    // Gray conversion, then the adjustments and threshold asked for
+    fn develop(&self, frame: &mut Frame) {
+        if self.settings.mode != ScanMode::Color && frame.channels == 3 {
+            ImageProcessor::convert_to_grayscale(&mut frame.pixels, frame.width as u32, frame.height as u32);
+            frame.channels = 1;
+        }
+        if self.settings.brightness != 0 {
+            ImageProcessor::adjust_brightness(&mut frame.pixels, self.settings.brightness);
+        }
+        if self.settings.contrast != 0 {
+            ImageProcessor::adjust_contrast(&mut frame.pixels, self.settings.contrast);
+        }
+        if self.settings.gamma != 1.0 && self.settings.gamma > 0.0 {
+            ImageProcessor::apply_gamma(&mut frame.pixels, self.settings.gamma);
+        }
+        if self.line_art() {
+            ImageProcessor::apply_threshold(&mut frame.pixels, self.settings.threshold);
+        }
+    }
+
+    fn add_frame(&mut self, mut frame: Frame) -> Result<(), &'static str> {
+        self.develop(&mut frame);
+        let (width, height) = (frame.width as u32, frame.height as u32);
+        if self.settings.enable_blank_detection
+            && ImageProcessor::detect_blank_page(&frame.pixels, width, height, BLANK_THRESHOLD)
+        {
+            crate::serial_println!("scan: job {} left out a blank page", self.job_id);
+            return Ok(());
+        }
+        self.pages += 1;
+        match self.settings.format {
+            ImageFormat::RAW => self.save("pnm", &formats::pnm(&frame, self.line_art())),
+            ImageFormat::JPEG => {
+                let jpeg = super::jpeg::encode(&frame, self.settings.compression_quality, self.settings.resolution);
+                self.save("jpg", &jpeg)
+            }
+            ImageFormat::PDF => {
+                let quality = self.settings.compression_quality;
+                self.pdf_pages.push(PdfPage::new(&frame, self.settings.resolution, self.line_art(), quality));
+                Ok(())
+            }
+            _ => Err("raw frames can only be saved as raw, JPEG or PDF"),
+        }
+    }
+
+    fn add_document(&mut self, content_type: &str, body: Vec<u8>) -> Result<(), &'static str> {
+        if self.settings.format == ImageFormat::RAW {
+            return self.add_frame(formats::decode_png(&body)?);
+        }
+        self.pages += 1;
+        let extension = match content_type.split(';').next().unwrap_or("").trim() {
+            "image/jpeg" => "jpg",
+            "application/pdf" => "pdf",
+            "image/png" => "png",
+            "image/tiff" => "tif",
+            _ => "bin",
+        };
+        self.save(extension, &body)
+    }
+
+    fn finish(&mut self) -> Result<bool, &'static str> {
+        if !self.pdf_pages.is_empty() {
+            let pdf = formats::pdf(&self.pdf_pages);
+            self.pdf_pages.clear();
+            let path = format!("{}/scan{}.pdf", self.directory, self.job_id);
+            write(&path, &pdf)?;
+            self.files.push(path);
+        }
+        Ok(true)
+    }
+
+    fn save(&mut self, extension: &str, data: &[u8]) -> Result<(), &'static str> {
+        let path = format!("{}/scan{}-{}.{}", self.directory, self.job_id, self.pages, extension);
+        write(&path, data)?;
+        self.files.push(path);
+        Ok(())
+    }
+}
+
+fn write(path: &str, data: &[u8]) -> Result<(), &'static str> {
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    for (slash, _) in path.match_indices('/').skip(1) {
+        let _ = vfs.create_directory_unchecked(&path[..slash]);
+    }
+    vfs.write_file_unchecked(path, data).map_err(|_| "could not write the scanned image")
+}
@@ -1,5 +1,5 @@
 use alloc::{vec::Vec, string::String};
-use super::{Scanner, ScannerCapabilities, ScanSettings, ScanMode, ScanSource, ImageFormat, ScannerStatus};
+use super::{Scanner, ScanSettings};
 
 pub struct ScannerBackend {
     sane_backend: super::sane::SANEBackend,
@@ -19,43 +19,9 @@ impl ScannerBackend {
         Ok(())
     }
 
+    /// The SANE devices; network and USB scanners are added by the subsystem
     pub fn discover_devices(&mut self) -> Result<Vec<Scanner>, &'static str> {
-        let mut scanners = Vec::new();
-        
-        scanners.push(Scanner {
-            id: 1,
-            name: String::from("Virtual Scanner"),
-            vendor: String::from("Generic"),
-            model: String::from("Virtual Scanner 1.0"),
-            device_type: String::from("virtual"),
-            status: ScannerStatus::Idle,
-            capabilities: ScannerCapabilities {
-                sources: vec![ScanSource::Flatbed, ScanSource::ADF],
-                modes: vec![ScanMode::Color, ScanMode::Grayscale, ScanMode::Lineart],
-                resolutions: vec![75, 100, 150, 200, 300, 600, 1200, 2400],
-                max_width: 216.0,
-                max_height: 356.0,
-                bit_depths: vec![1, 8, 16, 24],
-                supports_duplex: true,
-                supports_preview: true,
-                supports_ocr: true,
-                formats: vec![
-                    ImageFormat::JPEG,
-                    ImageFormat::PNG,
-                    ImageFormat::TIFF,
-                    ImageFormat::PDF,
-                    ImageFormat::BMP,
-                ],
-            },
-            current_settings: ScanSettings::default(),
-        });
-        
-        let sane_devices = self.sane_backend.get_devices()?;
-        for device in sane_devices {
-            scanners.push(device);
-        }
-        
-        Ok(scanners)
+        self.sane_backend.get_devices()
     }
 
     pub fn start_scan(&mut self, scanner_id: u32, settings: ScanSettings) -> Result<(), &'static str> {
@@ -89,6 +55,10 @@ impl ScannerBackend {
     pub fn get_scan_parameters(&self, scanner_id: u32) -> Result<ScanParameters, &'static str> {
         self.sane_backend.get_parameters(scanner_id)
     }
+
+    pub fn next_page(&mut self, scanner_id: u32) -> bool {
+        self.sane_backend.next_page(scanner_id)
+    }
 }
 
 #[derive(Debug, Clone)]
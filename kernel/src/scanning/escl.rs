@@ -0,0 +1,402 @@
+//! eSCL (AirScan), the HTTP and XML protocol of network scanners and of
+//! multifunction devices scanning over IPP-over-USB
+//!
+//! A scan is three kinds of request: POST the settings to ScanJobs, which
+//! answers 201 with the job's URL in Location; GET NextDocument under
+//! that once per page until 404 says there are no more; DELETE the job to
+//! cancel it. Each is a `Request` moved on by `poll`, so nothing waits on
+//! the scanner.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::printing::protocols::jetdirect::Stream;
+use crate::time::clocksource::now_ns;
+
+use super::{ImageFormat, ScanMode, ScanSettings, ScanSource, ScannerCapabilities};
+
+pub const PORT: u16 = 80;
+pub const ROOT: &str = "/eSCL";
+
+// How long a scanner has to answer; a page at high resolution takes a while
+const RESPONSE_TIMEOUT_NS: u64 = 120_000_000_000;
+// Most read from the bulk-in pipe at once
+const USB_CHUNK: usize = 16384;
+// eSCL measures the page in 1/300"
+const UNITS_PER_MM: f32 = 300.0 / 25.4;
+
+/// Where a scanner's eSCL service is
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Network { host: String, port: u16, root: String },
+    /// HTTP on the bulk pipes of an IPP-over-USB interface
+    Usb { address: u8, bulk_in: u8, bulk_out: u8 },
+}
+
+impl Endpoint {
+    /// `escl://host[:port][/root]` (also `airscan:` and `http:`), port 80 and
+    /// root /eSCL by default, or `usb:address` for an IPP-over-USB device
+    pub fn parse(uri: &str) -> Result<Endpoint, &'static str> {
+        if let Some(address) = uri.strip_prefix("usb:") {
+            let address = address.parse().map_err(|_| "bad USB address")?;
+            let (bulk_in, bulk_out) = super::ippusb::pipes(address).ok_or("no IPP-over-USB device there")?;
+            return Ok(Endpoint::Usb { address, bulk_in, bulk_out });
+        }
+        let (scheme, rest) = uri.split_once("://").ok_or("scanner URI needs a scheme")?;
+        match scheme {
+            "escl" | "airscan" | "http" => {}
+            "escls" | "https" => return Err("eSCL over TLS is not supported"),
+            _ => return Err("unknown scanner URI scheme"),
+        }
+        let (authority, root) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ROOT),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "bad port in scanner URI")?),
+            None => (authority, PORT),
+        };
+        if host.is_empty() {
+            return Err("scanner URI has no host");
+        }
+        Ok(Endpoint::Network { host: host.to_string(), port, root: root.to_string() })
+    }
+
+    /// The path the service's resources are under
+    pub fn root(&self) -> &str {
+        match self {
+            Endpoint::Network { root, .. } => root,
+            Endpoint::Usb { .. } => ROOT,
+        }
+    }
+
+    fn host_header(&self) -> String {
+        match self {
+            Endpoint::Network { host, port, .. } => format!("{}:{}", host, port),
+            // What IPP-over-USB devices expect
+            Endpoint::Usb { .. } => String::from("localhost"),
+        }
+    }
+}
+
+/// A response once all of it has arrived
+pub struct Response {
+    pub status: u16,
+    pub location: Option<String>,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+enum Pipe {
+    // A connection per request, closed by the scanner once it has answered
+    Tcp(Stream),
+    // IPP-over-USB keeps the pipes open; the response says where it ends
+    Usb { address: u8, bulk_in: u8 },
+}
+
+/// One HTTP request and its response
+pub struct Request {
+    pipe: Pipe,
+    response: Vec<u8>,
+    sent_at: Option<u64>,
+}
+
+impl Request {
+    /// Send `method` for `path`, with a body of the given type if any
+    pub fn new(
+        endpoint: &Endpoint,
+        method: &str,
+        path: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> Result<Request, &'static str> {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, endpoint.host_header());
+        if let Some((content_type, body)) = body {
+            request.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n", content_type, body.len()));
+        }
+        if let Endpoint::Network { .. } = endpoint {
+            request.push_str("Connection: close\r\n");
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        if let Some((_, body)) = body {
+            request.extend_from_slice(body);
+        }
+
+        let pipe = match endpoint {
+            Endpoint::Network { host, port, .. } => Pipe::Tcp(Stream::connect(host, *port, request)?),
+            Endpoint::Usb { address, bulk_in, bulk_out } => {
+                for chunk in request.chunks_mut(USB_CHUNK) {
+                    crate::usb::bulk_transfer(*address, *bulk_out, chunk, true)?;
+                }
+                Pipe::Usb { address: *address, bulk_in: *bulk_in }
+            }
+        };
+        let sent_at = matches!(pipe, Pipe::Usb { .. }).then(now_ns);
+        Ok(Request { pipe, response: Vec::new(), sent_at })
+    }
+
+    /// The response once it is complete
+    pub fn poll(&mut self) -> Result<Option<Response>, &'static str> {
+        let closed = match &mut self.pipe {
+            Pipe::Tcp(stream) => {
+                if !stream.poll()? {
+                    return Ok(None);
+                }
+                let closed = stream.peer_closed();
+                loop {
+                    let chunk = stream.receive();
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    self.response.extend_from_slice(&chunk);
+                }
+                closed
+            }
+            Pipe::Usb { address, bulk_in } => {
+                let mut buffer = alloc::vec![0u8; USB_CHUNK];
+                // A device with nothing to say yet lets the transfer time out
+                if let Ok(count) = crate::usb::bulk_transfer(*address, *bulk_in, &mut buffer, false) {
+                    self.response.extend_from_slice(&buffer[..count]);
+                }
+                false
+            }
+        };
+        let now = now_ns();
+        let sent_at = *self.sent_at.get_or_insert(now);
+        if let Some(response) = parse_response(&self.response, closed) {
+            return response.map(Some);
+        }
+        if closed {
+            return Err("scanner closed the connection without answering");
+        }
+        if now.saturating_sub(sent_at) > RESPONSE_TIMEOUT_NS {
+            return Err("scanner did not answer");
+        }
+        Ok(None)
+    }
+}
+
+// A whole HTTP response, None while more of it is to come
+fn parse_response(data: &[u8], closed: bool) -> Option<Result<Response, &'static str>> {
+    let end = data.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let header = String::from_utf8_lossy(&data[..end]);
+    let mut lines = header.split("\r\n");
+    let Some(status) = lines.next().and_then(|line| line.split_whitespace().nth(1)?.parse().ok()) else {
+        return Some(Err("scanner sent a bad HTTP response"));
+    };
+    let (mut length, mut chunked, mut location, mut content_type) = (None, false, None, String::new());
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse::<usize>().ok(),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "location" => location = Some(value.to_string()),
+            "content-type" => content_type = value.to_ascii_lowercase(),
+            _ => {}
+        }
+    }
+
+    let body = &data[end + 4..];
+    let body = if chunked {
+        let mut decoded = Vec::new();
+        let mut pos = 0;
+        loop {
+            let line = body[pos..].windows(2).position(|w| w == b"\r\n")?;
+            let size = core::str::from_utf8(&body[pos..pos + line]).ok()?;
+            let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+            pos += line + 2;
+            if size == 0 {
+                break decoded;
+            }
+            decoded.extend_from_slice(body.get(pos..pos + size)?);
+            pos += size + 2;
+        }
+    } else if let Some(length) = length {
+        body.get(..length)?.to_vec()
+    } else if status == 204 || status == 304 || closed {
+        body.to_vec()
+    } else {
+        return None;
+    };
+    Some(Ok(Response { status, location, content_type, body }))
+}
+
+/// The path part of the job URL a scanner gave in Location
+pub fn job_path(location: &str) -> String {
+    let path = match location.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None => location,
+    };
+    path.trim_end_matches('/').to_string()
+}
+
+// The text of each element with this local name, whatever its namespace
+// prefix, taken without regard to nesting of elements of other names
+fn elements<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut pos = 0;
+    core::iter::from_fn(move || loop {
+        let start = pos + xml[pos..].find('<')?;
+        let tag_end = start + xml[start..].find('>')?;
+        pos = tag_end + 1;
+        let tag = &xml[start + 1..tag_end];
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        if tag.starts_with('/') || tag.ends_with('/') || tag_name.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        let close = format!("</{}>", tag_name);
+        let content_end = pos + xml[pos..].find(&close)?;
+        let content = &xml[pos..content_end];
+        pos = content_end + close.len();
+        return Some(content.trim());
+    })
+}
+
+fn element<'a>(xml: &'a str, name: &'a str) -> Option<&'a str> {
+    elements(xml, name).next()
+}
+
+fn mode_of(color_mode: &str) -> Option<ScanMode> {
+    match color_mode {
+        "RGB24" | "RGB48" => Some(ScanMode::Color),
+        "Grayscale8" | "Grayscale16" => Some(ScanMode::Grayscale),
+        "BlackAndWhite1" => Some(ScanMode::Lineart),
+        _ => None,
+    }
+}
+
+fn format_of(mime: &str) -> Option<ImageFormat> {
+    match mime {
+        "image/jpeg" => Some(ImageFormat::JPEG),
+        "application/pdf" => Some(ImageFormat::PDF),
+        "image/png" => Some(ImageFormat::PNG),
+        "image/tiff" => Some(ImageFormat::TIFF),
+        _ => None,
+    }
+}
+
+/// What the scanner is asked for: raw images come as PNG, to be decoded
+pub fn mime_of(format: ImageFormat) -> Option<&'static str> {
+    match format {
+        ImageFormat::JPEG => Some("image/jpeg"),
+        ImageFormat::PDF => Some("application/pdf"),
+        ImageFormat::PNG | ImageFormat::RAW => Some("image/png"),
+        ImageFormat::TIFF => Some("image/tiff"),
+        ImageFormat::BMP => None,
+    }
+}
+
+/// The capabilities in a ScannerCapabilities document
+pub fn parse_capabilities(xml: &str) -> Result<ScannerCapabilities, &'static str> {
+    let platen = element(xml, "Platen");
+    let adf = element(xml, "Adf");
+    // Everything but the sources comes from the first input's settings
+    let input = platen.or(adf).ok_or("scanner lists no input source")?;
+
+    let mut sources = Vec::new();
+    if platen.is_some() {
+        sources.push(ScanSource::Flatbed);
+    }
+    let duplex = adf.is_some_and(|adf| element(adf, "AdfDuplexInputCaps").is_some());
+    if adf.is_some() {
+        sources.push(ScanSource::ADF);
+        if duplex {
+            sources.push(ScanSource::ADFDuplex);
+        }
+    }
+
+    let mut modes = Vec::new();
+    for mode in elements(input, "ColorMode").filter_map(mode_of) {
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    }
+    let mut resolutions: Vec<u32> = elements(input, "XResolution").filter_map(|r| r.parse().ok()).collect();
+    resolutions.sort_unstable();
+    resolutions.dedup();
+    let mut formats = Vec::new();
+    for format in elements(input, "DocumentFormatExt").chain(elements(input, "DocumentFormat")).filter_map(format_of) {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    if formats.contains(&ImageFormat::PNG) {
+        formats.push(ImageFormat::RAW);
+    }
+    let mut bit_depths: Vec<u8> = modes
+        .iter()
+        .map(|mode| match mode {
+            ScanMode::Color => 24,
+            ScanMode::Grayscale => 8,
+            ScanMode::Lineart | ScanMode::Halftone => 1,
+        })
+        .collect();
+    bit_depths.sort_unstable();
+
+    let dimension = |name: &'static str| {
+        element(input, name).and_then(|v| v.parse::<f32>().ok()).map_or(0.0, |v| v / UNITS_PER_MM)
+    };
+    Ok(ScannerCapabilities {
+        sources,
+        modes,
+        resolutions,
+        max_width: dimension("MaxWidth"),
+        max_height: dimension("MaxHeight"),
+        bit_depths,
+        supports_duplex: duplex,
+        supports_preview: true,
+        supports_ocr: false,
+        formats,
+    })
+}
+
+/// The ScanSettings document asking for a scan
+pub fn scan_settings(settings: &ScanSettings) -> Result<String, &'static str> {
+    let mime = mime_of(settings.format).ok_or("scanner cannot deliver that format")?;
+    let (source, duplex) = match settings.source {
+        ScanSource::Flatbed => ("Platen", false),
+        ScanSource::ADF => ("Feeder", false),
+        ScanSource::ADFDuplex => ("Feeder", true),
+        ScanSource::Film => return Err("eSCL cannot scan film"),
+    };
+    let color_mode = match settings.mode {
+        ScanMode::Color => "RGB24",
+        ScanMode::Grayscale => "Grayscale8",
+        ScanMode::Lineart | ScanMode::Halftone => "BlackAndWhite1",
+    };
+    let units = |mm: f32| (mm * UNITS_PER_MM) as u32;
+    let area = &settings.area;
+    Ok(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <scan:ScanSettings xmlns:scan=\"http://schemas.hp.com/imaging/escl/2011/05/03\" \
+         xmlns:pwg=\"http://www.pwg.org/schemas/2010/12/sm\">\n\
+         <pwg:Version>2.6</pwg:Version>\n\
+         <pwg:ScanRegions><pwg:ScanRegion>\
+         <pwg:ContentRegionUnits>escl:ThreeHundredthsOfInches</pwg:ContentRegionUnits>\
+         <pwg:XOffset>{}</pwg:XOffset><pwg:YOffset>{}</pwg:YOffset>\
+         <pwg:Width>{}</pwg:Width><pwg:Height>{}</pwg:Height>\
+         </pwg:ScanRegion></pwg:ScanRegions>\n\
+         <pwg:InputSource>{}</pwg:InputSource>\n\
+         <scan:Duplex>{}</scan:Duplex>\n\
+         <scan:ColorMode>{}</scan:ColorMode>\n\
+         <scan:XResolution>{}</scan:XResolution><scan:YResolution>{}</scan:YResolution>\n\
+         <pwg:DocumentFormat>{}</pwg:DocumentFormat><scan:DocumentFormatExt>{}</scan:DocumentFormatExt>\n\
+         </scan:ScanSettings>\n",
+        units(area.x),
+        units(area.y),
+        units(area.width),
+        units(area.height),
+        source,
+        duplex,
+        color_mode,
+        settings.resolution,
+        settings.resolution,
+        mime,
+        mime
+    ))
+}
@@ -0,0 +1,196 @@
+// Image files a scan is saved as, besides JPEG
+//
+// Raw frames become PNM: P6 for color, P5 for gray and P4 for line art.
+// PDF pages hold a JPEG each, or a 1-bit image for line art. PNG is only
+// read, for scanners that deliver raw images that way.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::printing::raster::inflate::{inflate, unfilter};
+
+use super::acquire::Frame;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1A\n";
+
+/// A frame as PNM, line art packed a bit to a pixel with 1 for black
+pub fn pnm(frame: &Frame, line_art: bool) -> Vec<u8> {
+    let (width, height) = (frame.width, frame.height);
+    let mut out = match (line_art, frame.channels) {
+        (true, _) => format!("P4\n{} {}\n", width, height),
+        (false, 3) => format!("P6\n{} {}\n255\n", width, height),
+        (false, _) => format!("P5\n{} {}\n255\n", width, height),
+    }
+    .into_bytes();
+    if line_art {
+        for row in frame.pixels.chunks(width * frame.channels) {
+            out.extend(pack_bits(row, frame.channels, false));
+        }
+    } else {
+        out.extend_from_slice(&frame.pixels);
+    }
+    out
+}
+
+// A row a bit to a pixel, set for white or for black
+fn pack_bits(row: &[u8], channels: usize, white: bool) -> Vec<u8> {
+    let mut packed = alloc::vec![0u8; (row.len() / channels).div_ceil(8)];
+    for (x, pixel) in row.chunks(channels).enumerate() {
+        if (pixel[0] >= 128) == white {
+            packed[x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    packed
+}
+
+/// A page of a PDF being put together
+pub enum PdfImage {
+    Jpeg { data: Vec<u8>, color: bool },
+    LineArt { data: Vec<u8> },
+}
+
+pub struct PdfPage {
+    pub width: usize,
+    pub height: usize,
+    pub dpi: u32,
+    pub image: PdfImage,
+}
+
+impl PdfPage {
+    pub fn new(frame: &Frame, dpi: u32, line_art: bool, quality: u8) -> PdfPage {
+        let image = if line_art {
+            let mut data = Vec::with_capacity(frame.width.div_ceil(8) * frame.height);
+            for row in frame.pixels.chunks(frame.width * frame.channels) {
+                data.extend(pack_bits(row, frame.channels, true));
+            }
+            PdfImage::LineArt { data }
+        } else {
+            PdfImage::Jpeg { data: super::jpeg::encode(frame, quality, dpi), color: frame.channels == 3 }
+        };
+        PdfPage { width: frame.width, height: frame.height, dpi: dpi.max(1), image }
+    }
+}
+
+/// A PDF with one scanned page to each page, sized to the scan
+pub fn pdf(pages: &[PdfPage]) -> Vec<u8> {
+    let mut out = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::new();
+    let mut object = |out: &mut Vec<u8>, body: &[u8]| {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+        out.extend_from_slice(body);
+        out.extend_from_slice(b"\nendobj\n");
+    };
+
+    // Objects 1 and 2 are the catalog and page tree, then three per page
+    let kids: Vec<String> = (0..pages.len()).map(|i| format!("{} 0 R", 3 + i * 3)).collect();
+    object(&mut out, b"<< /Type /Catalog /Pages 2 0 R >>");
+    object(&mut out, format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).as_bytes());
+    for (i, page) in pages.iter().enumerate() {
+        let number = 3 + i * 3;
+        let points = |dots: usize| dots as f32 * 72.0 / page.dpi as f32;
+        let (width, height) = (points(page.width), points(page.height));
+        object(
+            &mut out,
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << /Im0 {} 0 R >> >> \
+                 /Contents {} 0 R >>",
+                width,
+                height,
+                number + 2,
+                number + 1
+            )
+            .as_bytes(),
+        );
+        let contents = format!("q {:.2} 0 0 {:.2} 0 0 cm /Im0 Do Q", width, height);
+        object(&mut out, format!("<< /Length {} >>\nstream\n{}\nendstream", contents.len(), contents).as_bytes());
+
+        let (filter, space, bits, data) = match &page.image {
+            PdfImage::Jpeg { data, color } => {
+                (" /Filter /DCTDecode", if *color { "DeviceRGB" } else { "DeviceGray" }, 8, data)
+            }
+            PdfImage::LineArt { data } => ("", "DeviceGray", 1, data),
+        };
+        let mut image = format!(
+            "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /{} /BitsPerComponent {}{} \
+             /Length {} >>\nstream\n",
+            page.width,
+            page.height,
+            space,
+            bits,
+            filter,
+            data.len()
+        )
+        .into_bytes();
+        image.extend_from_slice(data);
+        image.extend_from_slice(b"\nendstream");
+        object(&mut out, &image);
+    }
+
+    let xref = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes());
+    for offset in &offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", offsets.len() + 1, xref).as_bytes(),
+    );
+    out
+}
+
+/// A PNG as 8-bit gray or RGB samples; deeper samples keep their high
+/// byte, and palettes, alpha and interlacing are not taken
+pub fn decode_png(data: &[u8]) -> Result<Frame, &'static str> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return Err("not a PNG image");
+    }
+    let mut pos = PNG_SIGNATURE.len();
+    let (mut header, mut compressed) = (None, Vec::new());
+    while let Some(length) = data.get(pos..pos + 4) {
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let kind = data.get(pos + 4..pos + 8).ok_or("PNG ends early")?;
+        let body = data.get(pos + 8..pos + 8 + length).ok_or("PNG ends early")?;
+        match kind {
+            b"IHDR" if length >= 13 => header = Some(body),
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + length;
+    }
+    let header = header.ok_or("PNG has no header")?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, color_type, interlace) = (header[8] as usize, header[9], header[12]);
+    let channels = match color_type {
+        0 => 1,
+        2 => 3,
+        _ => return Err("PNG is not gray or RGB"),
+    };
+    if interlace != 0 || !matches!(depth, 1 | 2 | 4 | 8 | 16) || (channels == 3 && depth < 8) {
+        return Err("PNG layout not supported");
+    }
+
+    let bits = channels * depth;
+    let row_length = (width * bits).div_ceil(8);
+    let rows = unfilter(&inflate(&compressed)?, bits.div_ceil(8), row_length);
+    if rows.len() < row_length * height {
+        return Err("PNG image data ends early");
+    }
+    let mut pixels = Vec::with_capacity(width * height * channels);
+    for row in rows.chunks(row_length).take(height) {
+        match depth {
+            8 => pixels.extend_from_slice(&row[..width * channels]),
+            16 => pixels.extend(row.chunks(2).take(width * channels).map(|sample| sample[0])),
+            _ => {
+                // Gray packed several to a byte, scaled up to 0-255
+                let max = (1 << depth) - 1;
+                for x in 0..width {
+                    let bit = x * depth;
+                    let value = (row[bit / 8] >> (8 - depth - bit % 8)) as usize & max;
+                    pixels.push((value * 255 / max) as u8);
+                }
+            }
+        }
+    }
+    Ok(Frame { width, height, channels, pixels })
+}
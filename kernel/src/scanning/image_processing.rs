@@ -11,8 +11,11 @@ impl ImageProcessor {
         Ok(())
     }
 
+    /// Whether fewer than `threshold` (a fraction) of the samples are dark
     pub fn detect_blank_page(image_data: &[u8], width: u32, height: u32, threshold: f32) -> bool {
-        false
+        let samples = image_data.len().max(1);
+        let dark = image_data.iter().filter(|&&sample| sample < 128).count();
+        (dark as f32) < threshold * samples as f32
     }
 
     pub fn adjust_brightness(image_data: &mut [u8], brightness: i32) {
@@ -0,0 +1,95 @@
+//! IPP-over-USB (USB printer class, subclass 1, protocol 4)
+//!
+//! Multifunction devices that print over IPP and scan over eSCL on the
+//! network often offer the same over USB: HTTP requests written to one
+//! bulk pipe, responses read from the other. Each device bound here is
+//! known to the scanning subsystem as `usb:<address>` and added as a
+//! scanner, its capabilities asked for as a network scanner's are.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::usb::USB_CLASS_PRINTER;
+
+const SUBCLASS_PRINTER: u8 = 1;
+const PROTOCOL_IPP_USB: u8 = 4;
+
+struct Bound {
+    device: DeviceId,
+    address: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+}
+
+static BOUND: Mutex<Vec<Bound>> = Mutex::new(Vec::new());
+
+/// The bulk-in and bulk-out endpoints of the device at a USB address
+pub fn pipes(address: u8) -> Option<(u8, u8)> {
+    BOUND.lock().iter().find(|b| b.address == address).map(|b| (b.bulk_in, b.bulk_out))
+}
+
+/// The addresses of the devices bound
+pub fn addresses() -> Vec<u8> {
+    BOUND.lock().iter().map(|b| b.address).collect()
+}
+
+pub static USB_DRIVER: IppUsbDriver = IppUsbDriver;
+
+pub struct IppUsbDriver;
+
+impl Driver for IppUsbDriver {
+    fn name(&self) -> &'static str {
+        "ipp_usb"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        // The device reports its first interface, which is often a plain
+        // printer one; the IPP-over-USB interfaces come after it
+        &[Match::UsbClass { class: USB_CLASS_PRINTER, subclass: Some(SUBCLASS_PRINTER), protocol: None }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        // Devices have two or more such interfaces so requests can overlap;
+        // one is enough here
+        let interface = usb
+            .interfaces
+            .iter()
+            .find(|interface| {
+                interface.class == USB_CLASS_PRINTER
+                    && interface.subclass == SUBCLASS_PRINTER
+                    && interface.protocol == PROTOCOL_IPP_USB
+                    && interface.bulk_in().is_some()
+                    && interface.bulk_out().is_some()
+            })
+            .ok_or(ProbeError::NoDevice)?;
+        if interface.alternate != 0 {
+            crate::usb::set_interface(address, interface.number, interface.alternate).map_err(ProbeError::Failed)?;
+        }
+        let (bulk_in, bulk_out) = (interface.bulk_in().unwrap().address, interface.bulk_out().unwrap().address);
+        BOUND.lock().push(Bound { device: device.id, address, bulk_in, bulk_out });
+
+        crate::serial_println!("ipp_usb: USB address {}, interface {}", address, interface.number);
+        super::usb_attached(address);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        let mut bound = BOUND.lock();
+        let Some(index) = bound.iter().position(|b| b.device == device.id) else {
+            return;
+        };
+        let address = bound.remove(index).address;
+        drop(bound);
+        super::usb_detached(address);
+    }
+}
@@ -0,0 +1,306 @@
+// Baseline JPEG encoder for scanned pages
+//
+// Sequential DCT with the example quantization and Huffman tables of the
+// standard's Annex K, no chroma subsampling. The forward DCT is the AAN
+// float one, its output scale folded into the quantization divisors.
+
+use alloc::vec::Vec;
+
+use super::acquire::Frame;
+
+// Zigzag position of each coefficient, in natural order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 5, 6, 14, 15, 27, 28, 2, 4, 7, 13, 16, 26, 29, 42, 3, 8, 12, 17, 25, 30, 41, 43, 9, 11, 18, 24, 31, 40, 44,
+    53, 10, 19, 23, 32, 39, 45, 52, 54, 20, 22, 33, 38, 46, 51, 55, 60, 21, 34, 37, 47, 50, 56, 59, 61, 35, 36, 48,
+    49, 57, 58, 62, 63,
+];
+
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51,
+    87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99,
+];
+
+// Codes of each length from 1 to 16 bits, then the symbols in code order
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14,
+    0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09,
+    0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A,
+    0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65,
+    0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88,
+    0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9,
+    0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+    0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA,
+    0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+];
+
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32,
+    0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0, 0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16,
+    0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39,
+    0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86,
+    0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8,
+    0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9,
+    0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+];
+
+// The AAN DCT leaves coefficient (u, v) scaled by these for u and v, and by 8
+const AAN_SCALE: [f32; 8] = [1.0, 1.387_039_8, 1.306_563, 1.175_875_6, 1.0, 0.785_695, 0.541_196_1, 0.275_899_38];
+
+// A Huffman table as a code and its length for each symbol
+struct Huffman {
+    codes: [(u16, u8); 256],
+}
+
+impl Huffman {
+    fn new(bits: &[u8; 16], values: &[u8]) -> Huffman {
+        let mut codes = [(0, 0); 256];
+        let (mut code, mut k) = (0u16, 0);
+        for length in 1..=16 {
+            for _ in 0..bits[length - 1] {
+                codes[values[k] as usize] = (code, length as u8);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Huffman { codes }
+    }
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, code: u16, length: u8) {
+        self.buffer = (self.buffer << length) | code as u32 & ((1 << length) - 1);
+        self.count += length as u32;
+        while self.count >= 8 {
+            let byte = (self.buffer >> (self.count - 8)) as u8;
+            self.out.push(byte);
+            // A 0xFF in the data is followed by a 0 so it is not read as a marker
+            if byte == 0xFF {
+                self.out.push(0);
+            }
+            self.count -= 8;
+        }
+    }
+
+    // Pad the last byte with 1 bits
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.put(0x7F, 8 - self.count as u8);
+        }
+    }
+}
+
+// A coefficient's magnitude category and the bits that follow it
+fn category(value: i32) -> (u8, u16) {
+    let magnitude = value.unsigned_abs();
+    let size = (32 - magnitude.leading_zeros()) as u8;
+    let bits = if value < 0 { (value - 1) as u16 } else { value as u16 };
+    (size, bits)
+}
+
+fn forward_dct(block: &mut [f32; 64]) {
+    for pass in 0..2 {
+        for i in 0..8 {
+            // Rows first, then columns
+            let index = |k: usize| if pass == 0 { i * 8 + k } else { k * 8 + i };
+            let d: [f32; 8] = core::array::from_fn(|k| block[index(k)]);
+            let (tmp0, tmp7) = (d[0] + d[7], d[0] - d[7]);
+            let (tmp1, tmp6) = (d[1] + d[6], d[1] - d[6]);
+            let (tmp2, tmp5) = (d[2] + d[5], d[2] - d[5]);
+            let (tmp3, tmp4) = (d[3] + d[4], d[3] - d[4]);
+
+            let (tmp10, tmp13) = (tmp0 + tmp3, tmp0 - tmp3);
+            let (tmp11, tmp12) = (tmp1 + tmp2, tmp1 - tmp2);
+            block[index(0)] = tmp10 + tmp11;
+            block[index(4)] = tmp10 - tmp11;
+            let z1 = (tmp12 + tmp13) * 0.707_106_77;
+            block[index(2)] = tmp13 + z1;
+            block[index(6)] = tmp13 - z1;
+
+            let (tmp10, tmp11, tmp12) = (tmp4 + tmp5, tmp5 + tmp6, tmp6 + tmp7);
+            let z5 = (tmp10 - tmp12) * 0.382_683_43;
+            let z2 = 0.541_196_1 * tmp10 + z5;
+            let z4 = 1.306_563 * tmp12 + z5;
+            let z3 = tmp11 * 0.707_106_77;
+            let (z11, z13) = (tmp7 + z3, tmp7 - z3);
+            block[index(5)] = z13 + z2;
+            block[index(3)] = z13 - z2;
+            block[index(1)] = z11 + z4;
+            block[index(7)] = z11 - z4;
+        }
+    }
+}
+
+// The example table scaled for a quality from 1 to 100, as libjpeg does
+fn scaled_table(base: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+    core::array::from_fn(|i| ((base[i] as u32 * scale + 50) / 100).clamp(1, 255) as u8)
+}
+
+struct Component {
+    divisors: [f32; 64],
+    dc: Huffman,
+    ac: Huffman,
+    previous_dc: i32,
+}
+
+impl Component {
+    fn new(table: &[u8; 64], dc: Huffman, ac: Huffman) -> Component {
+        let divisors = core::array::from_fn(|i| table[i] as f32 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8] * 8.0);
+        Component { divisors, dc, ac, previous_dc: 0 }
+    }
+
+    fn encode(&mut self, block: &mut [f32; 64], writer: &mut BitWriter) {
+        forward_dct(block);
+        let mut zigzag = [0i32; 64];
+        for (i, value) in block.iter().enumerate() {
+            let scaled = value / self.divisors[i];
+            zigzag[ZIGZAG[i]] = if scaled < 0.0 { (scaled - 0.5) as i32 } else { (scaled + 0.5) as i32 };
+        }
+
+        let (size, bits) = category(zigzag[0] - self.previous_dc);
+        self.previous_dc = zigzag[0];
+        let (code, length) = self.dc.codes[size as usize];
+        writer.put(code, length);
+        writer.put(bits, size);
+
+        let mut run = 0;
+        for &value in &zigzag[1..] {
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                let (code, length) = self.ac.codes[0xF0];
+                writer.put(code, length);
+                run -= 16;
+            }
+            let (size, bits) = category(value);
+            let (code, length) = self.ac.codes[(run << 4 | size) as usize];
+            writer.put(code, length);
+            writer.put(bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            let (code, length) = self.ac.codes[0x00];
+            writer.put(code, length);
+        }
+    }
+}
+
+fn segment(out: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn huffman_segment(out: &mut Vec<u8>, class_and_id: u8, bits: &[u8; 16], values: &[u8]) {
+    let mut body = Vec::with_capacity(17 + values.len());
+    body.push(class_and_id);
+    body.extend_from_slice(bits);
+    body.extend_from_slice(values);
+    segment(out, 0xC4, &body);
+}
+
+/// Compress a gray or RGB frame of 8-bit samples; `quality` is from 1 to 100
+pub fn encode(frame: &Frame, quality: u8, dpi: u32) -> Vec<u8> {
+    let color = frame.channels == 3;
+    let luma = scaled_table(&LUMA_QUANT, quality);
+    let chroma = scaled_table(&CHROMA_QUANT, quality);
+
+    let mut out = Vec::with_capacity(frame.pixels.len() / 8);
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    // JFIF 1.1 with the resolution in dots per inch
+    let dpi = (dpi.min(0xFFFF) as u16).to_be_bytes();
+    segment(&mut out, 0xE0, &[b'J', b'F', b'I', b'F', 0, 1, 1, 1, dpi[0], dpi[1], dpi[0], dpi[1], 0, 0]);
+
+    for (id, table) in [(0u8, &luma), (1, &chroma)].into_iter().take(if color { 2 } else { 1 }) {
+        let mut body = [0u8; 65];
+        body[0] = id;
+        for i in 0..64 {
+            body[1 + ZIGZAG[i]] = table[i];
+        }
+        segment(&mut out, 0xDB, &body);
+    }
+
+    let (width, height) = ((frame.width as u16).to_be_bytes(), (frame.height as u16).to_be_bytes());
+    let mut header = alloc::vec![8, height[0], height[1], width[0], width[1], frame.channels as u8];
+    for id in 0..frame.channels as u8 {
+        header.extend_from_slice(&[id + 1, 0x11, id.min(1)]);
+    }
+    segment(&mut out, 0xC0, &header);
+
+    huffman_segment(&mut out, 0x00, &DC_LUMA_BITS, &DC_VALUES);
+    huffman_segment(&mut out, 0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES);
+    if color {
+        huffman_segment(&mut out, 0x01, &DC_CHROMA_BITS, &DC_VALUES);
+        huffman_segment(&mut out, 0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES);
+    }
+
+    let mut scan = alloc::vec![frame.channels as u8];
+    for id in 0..frame.channels as u8 {
+        scan.extend_from_slice(&[id + 1, id.min(1) * 0x11]);
+    }
+    scan.extend_from_slice(&[0, 63, 0]);
+    segment(&mut out, 0xDA, &scan);
+
+    let luma_component = || {
+        let (dc, ac) = (Huffman::new(&DC_LUMA_BITS, &DC_VALUES), Huffman::new(&AC_LUMA_BITS, &AC_LUMA_VALUES));
+        Component::new(&luma, dc, ac)
+    };
+    let chroma_component = || {
+        let (dc, ac) = (Huffman::new(&DC_CHROMA_BITS, &DC_VALUES), Huffman::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES));
+        Component::new(&chroma, dc, ac)
+    };
+    let mut components = [luma_component(), chroma_component(), chroma_component()];
+    let mut writer = BitWriter { out, buffer: 0, count: 0 };
+    let mut blocks = [[0f32; 64]; 3];
+    for block_y in (0..frame.height).step_by(8) {
+        for block_x in (0..frame.width).step_by(8) {
+            for i in 0..64 {
+                // Edge blocks repeat the last row and column
+                let x = (block_x + i % 8).min(frame.width - 1);
+                let y = (block_y + i / 8).min(frame.height - 1);
+                let pixel = &frame.pixels[(y * frame.width + x) * frame.channels..][..frame.channels];
+                if color {
+                    let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                    blocks[0][i] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+                    blocks[1][i] = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+                    blocks[2][i] = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+                } else {
+                    blocks[0][i] = pixel[0] as f32 - 128.0;
+                }
+            }
+            for (component, block) in components.iter_mut().zip(blocks.iter_mut()).take(frame.channels) {
+                component.encode(block, &mut writer);
+            }
+        }
+    }
+    writer.flush();
+
+    let mut out = writer.out;
+    out.extend_from_slice(&[0xFF, 0xD9]);
+    out
+}
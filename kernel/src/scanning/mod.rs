@@ -1,11 +1,43 @@
+//! Scanning
+//!
+//! Scanners are SANE devices (the test device stands in for local ones),
+//! eSCL scanners on the network added by URI, and IPP-over-USB devices,
+//! which speak eSCL over their bulk pipes and are added as they are
+//! bound. A network or USB scanner's capabilities are asked for when it
+//! is added; it takes jobs once they are in. Jobs run from the main loop
+//! through `acquire`, which saves each page into the VFS.
+
 pub mod backend;
 pub mod sane;
 pub mod twain;
 pub mod image_processing;
+pub mod acquire;
+pub mod escl;
+pub mod formats;
+pub mod ippusb;
+pub mod jpeg;
 
-use alloc::{string::String, vec::Vec, collections::BTreeMap};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::RwLock;
 
+use crate::time::clocksource::now_ns;
+use acquire::Acquisition;
+use escl::{Endpoint, Request};
+
+// Scans move on a strip or a response at a time, this often at most
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+// Finished jobs kept for listing
+const HISTORY_LENGTH: usize = 50;
+/// Where scans are saved unless asked otherwise
+pub const DEFAULT_DIRECTORY: &str = "/scans";
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScannerStatus {
     Idle,
@@ -24,6 +56,27 @@ pub enum ScanMode {
     Halftone,
 }
 
+impl ScanMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScanMode::Color => "color",
+            ScanMode::Grayscale => "gray",
+            ScanMode::Lineart => "lineart",
+            ScanMode::Halftone => "halftone",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ScanMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "color" | "colour" => Some(ScanMode::Color),
+            "gray" | "grey" | "grayscale" => Some(ScanMode::Grayscale),
+            "lineart" | "bw" => Some(ScanMode::Lineart),
+            "halftone" => Some(ScanMode::Halftone),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScanSource {
     Flatbed,
@@ -32,6 +85,27 @@ pub enum ScanSource {
     Film,
 }
 
+impl ScanSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScanSource::Flatbed => "flatbed",
+            ScanSource::ADF => "adf",
+            ScanSource::ADFDuplex => "duplex",
+            ScanSource::Film => "film",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ScanSource> {
+        match name.to_ascii_lowercase().as_str() {
+            "flatbed" | "platen" => Some(ScanSource::Flatbed),
+            "adf" | "feeder" => Some(ScanSource::ADF),
+            "duplex" => Some(ScanSource::ADFDuplex),
+            "film" => Some(ScanSource::Film),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageFormat {
     JPEG,
@@ -42,6 +116,31 @@ pub enum ImageFormat {
     RAW,
 }
 
+impl ImageFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageFormat::JPEG => "jpeg",
+            ImageFormat::PNG => "png",
+            ImageFormat::TIFF => "tiff",
+            ImageFormat::PDF => "pdf",
+            ImageFormat::BMP => "bmp",
+            ImageFormat::RAW => "raw",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<ImageFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(ImageFormat::JPEG),
+            "png" => Some(ImageFormat::PNG),
+            "tiff" | "tif" => Some(ImageFormat::TIFF),
+            "pdf" => Some(ImageFormat::PDF),
+            "bmp" => Some(ImageFormat::BMP),
+            "raw" | "pnm" => Some(ImageFormat::RAW),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Scanner {
     pub id: u32,
@@ -49,6 +148,8 @@ pub struct Scanner {
     pub vendor: String,
     pub model: String,
     pub device_type: String,
+    /// `sane:<device>`, `usb:<address>` or an eSCL URL
+    pub uri: String,
     pub status: ScannerStatus,
     pub capabilities: ScannerCapabilities,
     pub current_settings: ScanSettings,
@@ -100,6 +201,7 @@ pub struct ScanArea {
 pub struct ScanJob {
     pub id: u32,
     pub scanner_id: u32,
+    pub user: String,
     pub settings: ScanSettings,
     pub status: ScanJobStatus,
     pub pages_scanned: u32,
@@ -118,9 +220,15 @@ pub enum ScanJobStatus {
 
 pub struct ScanSubsystem {
     scanners: RwLock<Vec<Scanner>>,
+    // Running jobs, then finished ones, newest first
     active_jobs: RwLock<Vec<ScanJob>>,
     backend: backend::ScannerBackend,
     job_counter: RwLock<u32>,
+    running: BTreeMap<u32, Acquisition>,
+    // Capability requests to network and USB scanners, by scanner id
+    queries: Vec<(u32, Request)>,
+    // Jobs being dropped on scanners after a cancel; their answers do not matter
+    cancels: Vec<Request>,
 }
 
 impl ScanSubsystem {
@@ -130,18 +238,28 @@ impl ScanSubsystem {
             active_jobs: RwLock::new(Vec::new()),
             backend: backend::ScannerBackend::new(),
             job_counter: RwLock::new(0),
+            running: BTreeMap::new(),
+            queries: Vec::new(),
+            cancels: Vec::new(),
         }
     }
 
     pub fn init(&mut self) -> Result<(), &'static str> {
         self.backend.init()?;
         self.discover_scanners()?;
+        // IPP-over-USB devices bound before the subsystem was up
+        for address in ippusb::addresses() {
+            self.add_usb_scanner(address);
+        }
         Ok(())
     }
 
+    /// Look for SANE devices again, keeping the network and USB scanners
     pub fn discover_scanners(&mut self) -> Result<(), &'static str> {
-        let scanners = self.backend.discover_devices()?;
-        *self.scanners.write() = scanners;
+        let found = self.backend.discover_devices()?;
+        let mut scanners = self.scanners.write();
+        scanners.retain(|s| !s.uri.starts_with("sane:"));
+        scanners.extend(found);
         Ok(())
     }
 
@@ -153,39 +271,201 @@ impl ScanSubsystem {
         self.scanners.read().iter().find(|s| s.id == id).cloned()
     }
 
-    pub fn start_scan(&mut self, scanner_id: u32, settings: ScanSettings) -> Result<u32, &'static str> {
+    pub fn find_scanner(&self, name: &str) -> Option<Scanner> {
+        self.scanners.read().iter().find(|s| s.name.eq_ignore_ascii_case(name)).cloned()
+    }
+
+    // A SANE device, scanned through the backend
+    fn is_local(&self, id: u32) -> bool {
+        self.scanners.read().iter().any(|s| s.id == id && s.uri.starts_with("sane:"))
+    }
+
+    fn set_status(&self, id: u32, status: ScannerStatus) {
+        if let Some(scanner) = self.scanners.write().iter_mut().find(|s| s.id == id) {
+            scanner.status = status;
+        }
+    }
+
+    /// Add an eSCL scanner by URI and ask it what it can do
+    pub fn add_network_scanner(&mut self, name: &str, uri: &str) -> Result<u32, &'static str> {
+        if self.find_scanner(name).is_some() {
+            return Err("a scanner by that name already exists");
+        }
+        let endpoint = Endpoint::parse(uri)?;
+        let device_type = if let Endpoint::Usb { .. } = endpoint { "usb" } else { "escl" };
+        let id = self.add_escl_scanner(name, uri, device_type);
+        self.query_capabilities(id, &endpoint)?;
+        Ok(id)
+    }
+
+    // A scanner whose capabilities are still to come
+    fn add_escl_scanner(&self, name: &str, uri: &str, device_type: &str) -> u32 {
+        let mut scanners = self.scanners.write();
+        // SANE devices are numbered from 100 up, these from 1
+        let id = scanners.iter().filter(|s| !s.uri.starts_with("sane:")).map(|s| s.id).max().unwrap_or(0) + 1;
+        scanners.push(Scanner {
+            id,
+            name: name.to_string(),
+            vendor: String::new(),
+            model: String::new(),
+            device_type: device_type.to_string(),
+            uri: uri.to_string(),
+            status: ScannerStatus::WarmingUp,
+            capabilities: ScannerCapabilities {
+                sources: Vec::new(),
+                modes: Vec::new(),
+                resolutions: Vec::new(),
+                max_width: 0.0,
+                max_height: 0.0,
+                bit_depths: Vec::new(),
+                supports_duplex: false,
+                supports_preview: false,
+                supports_ocr: false,
+                formats: Vec::new(),
+            },
+            current_settings: ScanSettings::default(),
+        });
+        id
+    }
+
+    fn query_capabilities(&mut self, id: u32, endpoint: &Endpoint) -> Result<(), &'static str> {
+        let path = format!("{}/ScannerCapabilities", endpoint.root());
+        match Request::new(endpoint, "GET", &path, None) {
+            Ok(request) => {
+                self.queries.push((id, request));
+                Ok(())
+            }
+            Err(e) => {
+                self.set_status(id, ScannerStatus::Offline);
+                Err(e)
+            }
+        }
+    }
+
+    /// Ask a network or USB scanner for its capabilities again
+    pub fn refresh_scanner(&mut self, id: u32) -> Result<(), &'static str> {
+        let scanner = self.get_scanner(id).ok_or("Scanner not found")?;
+        if scanner.uri.starts_with("sane:") {
+            return Ok(());
+        }
+        if scanner.status == ScannerStatus::Scanning {
+            return Err("Scanner is busy");
+        }
+        self.queries.retain(|(query, _)| *query != id);
+        self.set_status(id, ScannerStatus::WarmingUp);
+        self.query_capabilities(id, &Endpoint::parse(&scanner.uri)?)
+    }
+
+    fn add_usb_scanner(&mut self, address: u8) {
+        let uri = format!("usb:{}", address);
+        if self.scanners.read().iter().any(|s| s.uri == uri) {
+            return;
+        }
+        let product = crate::usb::device(address).map(|usb| usb.device_desc.product_index).unwrap_or(0);
+        let name = match crate::usb::string_descriptor(address, product) {
+            Ok(product) if product != "" && self.find_scanner(&product).is_none() => product,
+            _ => format!("usb{}", address),
+        };
+        let id = self.add_escl_scanner(&name, &uri, "usb");
+        let result = Endpoint::parse(&uri).and_then(|endpoint| self.query_capabilities(id, &endpoint));
+        if let Err(e) = result {
+            crate::serial_println!("scan: {} cannot be asked for its capabilities: {}", name, e);
+        }
+    }
+
+    /// Take a scanner away, cancelling its running job
+    pub fn remove_scanner(&mut self, id: u32) -> Result<(), &'static str> {
+        if self.get_scanner(id).is_none() {
+            return Err("Scanner not found");
+        }
+        let running: Vec<u32> = self.running.values().filter(|a| a.scanner_id == id).map(|a| a.job_id).collect();
+        for job_id in running {
+            self.cancel_scan(job_id)?;
+        }
+        self.queries.retain(|(query, _)| *query != id);
+        self.scanners.write().retain(|s| s.id != id);
+        Ok(())
+    }
+
+    /// Check settings against what the scanner says it can do
+    fn check_settings(scanner: &Scanner, settings: &ScanSettings) -> Result<(), &'static str> {
+        let capabilities = &scanner.capabilities;
+        if !capabilities.sources.contains(&settings.source) {
+            return Err("scanner has no such source");
+        }
+        if !capabilities.modes.contains(&settings.mode) {
+            return Err("scanner cannot scan in that mode");
+        }
+        if !capabilities.resolutions.contains(&settings.resolution) {
+            return Err("scanner does not offer that resolution");
+        }
+        if !capabilities.formats.contains(&settings.format) {
+            return Err("scanner cannot deliver that format");
+        }
+        Ok(())
+    }
+
+    /// Start a scan whose pages are saved under `directory`
+    pub fn start_scan(
+        &mut self,
+        scanner_id: u32,
+        mut settings: ScanSettings,
+        user: &str,
+        directory: &str,
+    ) -> Result<u32, &'static str> {
         let scanner = self.get_scanner(scanner_id).ok_or("Scanner not found")?;
         
-        if scanner.status != ScannerStatus::Idle {
-            return Err("Scanner is busy");
+        match scanner.status {
+            ScannerStatus::Idle => {}
+            ScannerStatus::WarmingUp => return Err("Scanner has not said yet what it can do"),
+            ScannerStatus::Offline | ScannerStatus::Error => return Err("Scanner is offline"),
+            _ => return Err("Scanner is busy"),
+        }
+        Self::check_settings(&scanner, &settings)?;
+        // The scan area ends where the glass or the feeder does
+        let capabilities = &scanner.capabilities;
+        if capabilities.max_width > 0.0 && capabilities.max_height > 0.0 {
+            let area = &mut settings.area;
+            area.width = area.width.min(capabilities.max_width - area.x.min(capabilities.max_width));
+            area.height = area.height.min(capabilities.max_height - area.y.min(capabilities.max_height));
         }
         
         let job_id = self.generate_job_id();
+        let acquisition = if scanner.uri.starts_with("sane:") {
+            self.backend.start_scan(scanner_id, settings.clone())?;
+            Acquisition::local(job_id, scanner_id, settings.clone(), directory, &self.backend)
+        } else {
+            Acquisition::escl(job_id, scanner_id, settings.clone(), directory, Endpoint::parse(&scanner.uri)?)
+        }?;
         let job = ScanJob {
             id: job_id,
             scanner_id,
+            user: user.to_string(),
             settings,
-            status: ScanJobStatus::Pending,
+            status: ScanJobStatus::InProgress,
             pages_scanned: 0,
             output_files: Vec::new(),
             error_message: None,
         };
         
-        self.active_jobs.write().push(job);
-        self.backend.start_scan(scanner_id, settings)?;
+        self.active_jobs.write().insert(0, job);
+        self.running.insert(job_id, acquisition);
+        self.set_status(scanner_id, ScannerStatus::Scanning);
         
         Ok(job_id)
     }
 
     pub fn cancel_scan(&mut self, job_id: u32) -> Result<(), &'static str> {
-        let mut jobs = self.active_jobs.write();
-        if let Some(job) = jobs.iter_mut().find(|j| j.id == job_id) {
-            job.status = ScanJobStatus::Cancelled;
-            self.backend.cancel_scan(job.scanner_id)?;
-            Ok(())
-        } else {
-            Err("Job not found")
+        let acquisition = self.running.remove(&job_id).ok_or("Job not running")?;
+        if let Some((endpoint, job)) = acquisition.escl_job() {
+            if let Ok(request) = Request::new(&endpoint, "DELETE", &job, None) {
+                self.cancels.push(request);
+            }
+        } else if self.is_local(acquisition.scanner_id) {
+            let _ = self.backend.cancel_scan(acquisition.scanner_id);
         }
+        self.finish(acquisition, ScanJobStatus::Cancelled, None);
+        Ok(())
     }
 
     pub fn get_scan_progress(&self, job_id: u32) -> Option<(u32, ScanJobStatus)> {
@@ -195,6 +475,15 @@ impl ScanSubsystem {
             .map(|j| (j.pages_scanned, j.status))
     }
 
+    /// Running jobs and those recently finished
+    pub fn list_jobs(&self) -> Vec<ScanJob> {
+        self.active_jobs.read().clone()
+    }
+
+    pub fn get_job(&self, job_id: u32) -> Option<ScanJob> {
+        self.active_jobs.read().iter().find(|j| j.id == job_id).cloned()
+    }
+
     pub fn preview_scan(&mut self, scanner_id: u32) -> Result<Vec<u8>, &'static str> {
         let mut settings = ScanSettings::default();
         settings.resolution = 75;
@@ -220,6 +509,88 @@ impl ScanSubsystem {
     pub fn calibrate_scanner(&mut self, scanner_id: u32) -> Result<(), &'static str> {
         self.backend.calibrate(scanner_id)
     }
+
+    /// Take in capability answers and move every running scan on
+    pub fn poll(&mut self) {
+        let mut index = 0;
+        while index < self.queries.len() {
+            let (id, request) = &mut self.queries[index];
+            let id = *id;
+            let answer = match request.poll() {
+                Ok(None) => {
+                    index += 1;
+                    continue;
+                }
+                Ok(Some(response)) if response.status == 200 => {
+                    escl::parse_capabilities(&String::from_utf8_lossy(&response.body))
+                }
+                Ok(Some(_)) => Err("scanner has no eSCL service there"),
+                Err(e) => Err(e),
+            };
+            self.queries.remove(index);
+            let mut scanners = self.scanners.write();
+            let Some(scanner) = scanners.iter_mut().find(|s| s.id == id) else {
+                continue;
+            };
+            match answer {
+                Ok(capabilities) => {
+                    scanner.capabilities = capabilities;
+                    scanner.status = ScannerStatus::Idle;
+                }
+                Err(e) => {
+                    crate::serial_println!("scan: {} is offline: {}", scanner.name, e);
+                    scanner.status = ScannerStatus::Offline;
+                }
+            }
+        }
+
+        self.cancels.retain_mut(|request| matches!(request.poll(), Ok(None)));
+
+        let mut done = Vec::new();
+        for (&job_id, acquisition) in self.running.iter_mut() {
+            let result = acquisition.step(&mut self.backend);
+            if let Some(job) = self.active_jobs.write().iter_mut().find(|j| j.id == job_id) {
+                job.pages_scanned = acquisition.pages;
+                job.output_files.clone_from(&acquisition.files);
+            }
+            match result {
+                Ok(false) => {}
+                Ok(true) => done.push((job_id, None)),
+                Err(e) => done.push((job_id, Some(e))),
+            }
+        }
+        for (job_id, error) in done {
+            if let Some(acquisition) = self.running.remove(&job_id) {
+                if error.is_some() && self.is_local(acquisition.scanner_id) {
+                    let _ = self.backend.cancel_scan(acquisition.scanner_id);
+                }
+                let status = if error.is_some() { ScanJobStatus::Failed } else { ScanJobStatus::Completed };
+                self.finish(acquisition, status, error);
+            }
+        }
+    }
+
+    fn finish(&mut self, acquisition: Acquisition, status: ScanJobStatus, error: Option<&'static str>) {
+        let mut jobs = self.active_jobs.write();
+        if let Some(job) = jobs.iter_mut().find(|j| j.id == acquisition.job_id) {
+            job.status = status;
+            job.pages_scanned = acquisition.pages;
+            job.output_files = acquisition.files;
+            job.error_message = error.map(String::from);
+            match error {
+                Some(e) => crate::serial_println!("scan: job {} failed: {}", job.id, e),
+                None => crate::serial_println!("scan: job {} {:?}, {} pages", job.id, status, job.pages_scanned),
+            }
+        }
+        // Keep the newest finished jobs
+        let mut finished = 0;
+        jobs.retain(|j| j.status == ScanJobStatus::InProgress || {
+            finished += 1;
+            finished <= HISTORY_LENGTH
+        });
+        drop(jobs);
+        self.set_status(acquisition.scanner_id, ScannerStatus::Idle);
+    }
 }
 
 impl Default for ScanSettings {
@@ -261,4 +632,33 @@ pub fn init() -> Result<(), &'static str> {
 
 pub fn get_subsystem() -> &'static RwLock<Option<ScanSubsystem>> {
     &SCAN_SUBSYSTEM
+}
+
+/// Move scans and capability requests on; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    if let Some(subsystem) = SCAN_SUBSYSTEM.write().as_mut() {
+        subsystem.poll();
+    }
+}
+
+// An IPP-over-USB device was bound; before `init` it is picked up there
+fn usb_attached(address: u8) {
+    if let Some(subsystem) = SCAN_SUBSYSTEM.write().as_mut() {
+        subsystem.add_usb_scanner(address);
+    }
+}
+
+fn usb_detached(address: u8) {
+    let uri = format!("usb:{}", address);
+    if let Some(subsystem) = SCAN_SUBSYSTEM.write().as_mut() {
+        if let Some(scanner) = subsystem.list_scanners().into_iter().find(|s| s.uri == uri) {
+            let _ = subsystem.remove_scanner(scanner.id);
+            crate::serial_println!("scan: {} disconnected", scanner.name);
+        }
+    }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use alloc::{vec::Vec, string::{String, ToString}, collections::BTreeMap};
+use alloc::{vec::Vec, string::{String, ToString}, collections::BTreeMap, format, vec};
 use super::{Scanner, ScanSettings, ScannerStatus};
 use super::backend::{ScanParameters, FrameFormat};
 
@@ -6,6 +6,13 @@ const SANE_VERSION_MAJOR: u32 = 1;
 const SANE_VERSION_MINOR: u32 = 0;
 const SANE_VERSION_BUILD: u32 = 27;
 
+// Scanner ids of SANE devices start here
+const FIRST_ID: u32 = 100;
+// Pages the test device's document feeder holds
+const ADF_PAGES: u32 = 3;
+// Most of a frame handed over by one read
+const READ_SIZE: usize = 65536;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SANEStatus {
     Good = 0,
@@ -82,8 +89,9 @@ pub struct SANEBackend {
 struct ScanState {
     device: SANEDevice,
     settings: ScanSettings,
-    buffer: Vec<u8>,
     bytes_read: usize,
+    // Pages done before the one being read
+    page: u32,
     is_scanning: bool,
 }
 
@@ -116,11 +124,12 @@ impl SANEBackend {
         let mut scanners = Vec::new();
         for (i, device) in self.devices.iter().enumerate() {
             scanners.push(Scanner {
-                id: (i + 100) as u32,
+                id: FIRST_ID + i as u32,
                 name: device.name.clone(),
                 vendor: device.vendor.clone(),
                 model: device.model.clone(),
                 device_type: device.device_type.clone(),
+                uri: format!("sane:{}", device.name),
                 status: ScannerStatus::Idle,
                 capabilities: self.get_device_capabilities(device)?,
                 current_settings: ScanSettings::default(),
@@ -146,24 +155,29 @@ impl SANEBackend {
 
     fn get_device_capabilities(&self, _device: &SANEDevice) -> Result<super::ScannerCapabilities, &'static str> {
         Ok(super::ScannerCapabilities {
-            sources: vec![super::ScanSource::Flatbed],
-            modes: vec![super::ScanMode::Color, super::ScanMode::Grayscale],
+            sources: vec![super::ScanSource::Flatbed, super::ScanSource::ADF, super::ScanSource::ADFDuplex],
+            modes: vec![super::ScanMode::Color, super::ScanMode::Grayscale, super::ScanMode::Lineart],
             resolutions: vec![75, 150, 300, 600],
             max_width: 216.0,
             max_height: 297.0,
-            bit_depths: vec![8, 16],
-            supports_duplex: false,
+            bit_depths: vec![1, 8, 24],
+            supports_duplex: true,
             supports_preview: true,
             supports_ocr: false,
-            formats: vec![super::ImageFormat::PNG, super::ImageFormat::JPEG],
+            // What the acquisition pipeline makes of its raw frames
+            formats: vec![super::ImageFormat::RAW, super::ImageFormat::JPEG, super::ImageFormat::PDF],
         })
     }
 
-    pub fn open_device(&mut self, scanner_id: u32) -> Result<(), &'static str> {
-        let device_index = (scanner_id - 100) as usize;
-        if device_index >= self.devices.len() {
-            return Err("Invalid scanner ID");
+    fn device_index(&self, scanner_id: u32) -> Result<usize, &'static str> {
+        match scanner_id.checked_sub(FIRST_ID) {
+            Some(index) if (index as usize) < self.devices.len() => Ok(index as usize),
+            _ => Err("Invalid scanner ID"),
         }
+    }
+
+    pub fn open_device(&mut self, scanner_id: u32) -> Result<(), &'static str> {
+        let device_index = self.device_index(scanner_id)?;
         
         self.devices[device_index].handle = Some(scanner_id);
         self.load_device_options(scanner_id)?;
@@ -172,10 +186,7 @@ impl SANEBackend {
     }
 
     pub fn close_device(&mut self, scanner_id: u32) -> Result<(), &'static str> {
-        let device_index = (scanner_id - 100) as usize;
-        if device_index >= self.devices.len() {
-            return Err("Invalid scanner ID");
-        }
+        let device_index = self.device_index(scanner_id)?;
         
         self.devices[device_index].handle = None;
         self.options.remove(&scanner_id);
@@ -221,10 +232,7 @@ impl SANEBackend {
             return Err("Scan already in progress");
         }
         
-        let device_index = (scanner_id - 100) as usize;
-        if device_index >= self.devices.len() {
-            return Err("Invalid scanner ID");
-        }
+        let device_index = self.device_index(scanner_id)?;
         
         if self.devices[device_index].handle.is_none() {
             self.open_device(scanner_id)?;
@@ -233,8 +241,8 @@ impl SANEBackend {
         let scan_state = ScanState {
             device: self.devices[device_index].clone(),
             settings,
-            buffer: Vec::with_capacity(1024 * 1024),
             bytes_read: 0,
+            page: 0,
             is_scanning: true,
         };
         
@@ -251,34 +259,60 @@ impl SANEBackend {
         }
     }
 
+    /// The next rows of the frame, nothing once all of it has been read
     pub fn read_data(&mut self, scanner_id: u32) -> Result<Vec<u8>, &'static str> {
-        if let Some(state) = self.active_scans.get_mut(&scanner_id) {
-            if !state.is_scanning {
-                return Err("Scan not in progress");
-            }
-            
-            let mut data = vec![0u8; 8192];
-            state.buffer.extend_from_slice(&data);
-            state.bytes_read += data.len();
-            
-            Ok(data)
-        } else {
-            Err("No active scan")
+        let parameters = self.get_parameters(scanner_id)?;
+        let state = self.active_scans.get_mut(&scanner_id).ok_or("No active scan")?;
+        if !state.is_scanning {
+            return Err("Scan not in progress");
+        }
+        
+        let line_length = parameters.bytes_per_line.max(1) as usize;
+        let first = state.bytes_read / line_length;
+        let last = (first + (READ_SIZE / line_length).max(1)).min(parameters.lines as usize);
+        let mut data = Vec::with_capacity((last - first) * line_length);
+        for y in first..last {
+            test_pattern_line(&parameters, state.page, y, &mut data);
         }
+        state.bytes_read += data.len();
+        Ok(data)
     }
 
+    /// Move on to the next page once a frame has been read, false when
+    /// there is none: a flatbed has one, the feeder `ADF_PAGES` sheets
+    pub fn next_page(&mut self, scanner_id: u32) -> bool {
+        let Some(state) = self.active_scans.get_mut(&scanner_id) else {
+            return false;
+        };
+        let pages = match state.settings.source {
+            super::ScanSource::ADF => ADF_PAGES,
+            super::ScanSource::ADFDuplex => ADF_PAGES * 2,
+            _ => 1,
+        };
+        if state.page + 1 >= pages {
+            return false;
+        }
+        state.page += 1;
+        state.bytes_read = 0;
+        true
+    }
+
+    /// The frame's layout: 8-bit RGB for color, 8-bit gray for the rest
     pub fn get_parameters(&self, scanner_id: u32) -> Result<ScanParameters, &'static str> {
         if let Some(state) = self.active_scans.get(&scanner_id) {
+            let format = match state.settings.mode {
+                super::ScanMode::Color => FrameFormat::RGB,
+                _ => FrameFormat::Gray,
+            };
+            let dots = |mm: f32| ((mm * state.settings.resolution as f32 / 25.4) as u32).max(1);
+            let pixels_per_line = dots(state.settings.area.width);
             Ok(ScanParameters {
-                format: match state.settings.mode {
-                    super::ScanMode::Color => FrameFormat::RGB,
-                    _ => FrameFormat::Gray,
-                },
+                format,
                 last_frame: true,
-                bytes_per_line: (state.settings.area.width as u32 * state.settings.resolution / 25) * 3,
-                pixels_per_line: state.settings.area.width as u32 * state.settings.resolution / 25,
-                lines: state.settings.area.height as u32 * state.settings.resolution / 25,
-                depth: state.settings.bit_depth,
+                bytes_per_line: pixels_per_line * if format == FrameFormat::RGB { 3 } else { 1 },
+                pixels_per_line,
+                lines: dots(state.settings.area.height),
+                depth: 8,
             })
         } else {
             Err("No active scan")
@@ -329,4 +363,38 @@ impl SANEBackend {
     pub fn calibrate(&mut self, scanner_id: u32) -> Result<(), &'static str> {
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// One line of the test device's page: eight bars from white to black over
+// the top three quarters, a gray ramp under them, and the page number as
+// that many dark squares along the top
+fn test_pattern_line(parameters: &ScanParameters, page: u32, y: usize, out: &mut Vec<u8>) {
+    const BARS: [[u8; 3]; 8] = [
+        [255, 255, 255],
+        [255, 255, 0],
+        [0, 255, 255],
+        [0, 255, 0],
+        [255, 0, 255],
+        [255, 0, 0],
+        [0, 0, 255],
+        [0, 0, 0],
+    ];
+    let (width, height) = (parameters.pixels_per_line as usize, parameters.lines as usize);
+    let square = (width / 16).max(1);
+    for x in 0..width {
+        let in_row = y >= square / 2 && y < square * 3 / 2;
+        let marker = in_row && x % (square * 2) >= square && x / (square * 2) <= page as usize;
+        let rgb = if marker {
+            [32, 32, 32]
+        } else if y < height * 3 / 4 {
+            BARS[x * 8 / width]
+        } else {
+            let level = (x * 255 / width.max(2).saturating_sub(1)) as u8;
+            [level, level, level]
+        };
+        match parameters.format {
+            FrameFormat::RGB => out.extend_from_slice(&rgb),
+            _ => out.push(((rgb[0] as u32 * 77 + rgb[1] as u32 * 150 + rgb[2] as u32 * 29) >> 8) as u8),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Bringing a card up: identification, then bus width and speed
+//!
+//! SD cards answer ACMD41 and, from version 2.00 on, CMD8; MMC and eMMC
+//! answer neither and are asked with CMD1 instead. Either way the card then
+//! gives its CID, is given an address, gives its CSD and is selected. SD
+//! cards go to 4 bits and, with CMD6, to high speed at 50 MHz; eMMC goes to
+//! 8 bits where the slot has them and to 52 MHz by writing EXT_CSD. The
+//! 1.8 V modes (UHS-I, HS200 and up) need voltage switching and tuning and
+//! are not used.
+
+use alloc::string::String;
+use alloc::vec;
+
+use super::{delay_ms, Buffer, Host, Response, Transfer, SECTOR_SIZE};
+
+// Commands; some numbers mean one thing to SD and another to MMC
+const GO_IDLE_STATE: u8 = 0;
+const SEND_OP_COND: u8 = 1;
+const ALL_SEND_CID: u8 = 2;
+const SEND_RELATIVE_ADDR: u8 = 3;
+const SWITCH: u8 = 6;
+const SELECT_CARD: u8 = 7;
+const SEND_IF_COND: u8 = 8;
+const SEND_EXT_CSD: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+pub const READ_SINGLE_BLOCK: u8 = 17;
+pub const READ_MULTIPLE_BLOCK: u8 = 18;
+pub const WRITE_BLOCK: u8 = 24;
+pub const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const APP_CMD: u8 = 55;
+// Application commands, each after APP_CMD
+const SET_BUS_WIDTH: u8 = 6;
+const SD_SEND_OP_COND: u8 = 41;
+
+// 2.7-3.6 V and the check pattern CMD8 echoes
+const IF_COND: u32 = 0x1AA;
+// OCR: 2.7-3.6 V, high capacity (or MMC sector addressing), and powered up
+const OCR_VOLTAGES: u32 = 0x00FF_8000;
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+const OCR_ACCESS_MODE: u32 = 3 << 29;
+const OCR_READY: u32 = 1 << 31;
+// The address MMC cards are given, as they do not pick their own
+const MMC_RCA: u16 = 1;
+
+// SWITCH_FUNC: set group 1 (access mode) to function 1, high speed
+const SD_SWITCH_HIGH_SPEED: u32 = 0x80FF_FFF1;
+// MMC SWITCH: write a byte of EXT_CSD
+const MMC_SWITCH_WRITE_BYTE: u32 = 3 << 24;
+
+// EXT_CSD fields
+const EXT_CSD_BUS_WIDTH: u8 = 183;
+const EXT_CSD_HS_TIMING: u8 = 185;
+const EXT_CSD_CARD_TYPE: usize = 196;
+const EXT_CSD_SEC_COUNT: usize = 212;
+const CARD_TYPE_HS_52: u8 = 1 << 1;
+
+const IDENTIFY_HZ: u32 = 400_000;
+const SD_DEFAULT_HZ: u32 = 25_000_000;
+const SD_HIGH_SPEED_HZ: u32 = 50_000_000;
+const MMC_DEFAULT_HZ: u32 = 26_000_000;
+const MMC_HIGH_SPEED_HZ: u32 = 52_000_000;
+
+// Asked 10 ms apart, a card has a second to power up
+const OP_COND_TRIES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Standard capacity, addressed in bytes
+    Sd,
+    /// High or extended capacity, addressed in sectors
+    Sdhc,
+    /// MMC or eMMC
+    Mmc,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Sd => "SD",
+            Kind::Sdhc => "SDHC/SDXC",
+            Kind::Mmc => "MMC",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Card {
+    pub kind: Kind,
+    pub rca: u16,
+    /// Addressed in sectors rather than bytes
+    pub high_capacity: bool,
+    pub sectors: u64,
+    /// Product name from the CID
+    pub name: String,
+    pub serial: u32,
+    pub bus_width: u8,
+    pub clock_hz: u32,
+    pub high_speed: bool,
+    pub read_only: bool,
+}
+
+impl Card {
+    /// The argument reads and writes of `sector` take
+    pub fn address(&self, sector: u64) -> u32 {
+        if self.high_capacity {
+            sector as u32
+        } else {
+            (sector * SECTOR_SIZE as u64) as u32
+        }
+    }
+}
+
+// `length` bits of a CID or CSD from bit `start` up. The controller drops
+// the CRC byte, so register bit `start` is bit `start - 8` of the response.
+fn bits(register: &[u32; 4], start: usize, length: usize) -> u32 {
+    let mut value = 0;
+    for i in 0..length {
+        let bit = start - 8 + i;
+        if register[bit / 32] >> (bit % 32) & 1 != 0 {
+            value |= 1 << i;
+        }
+    }
+    value
+}
+
+// `count` characters of a CID from bit `top` down
+fn cid_name(cid: &[u32; 4], top: usize, count: usize) -> String {
+    (0..count).map(|i| bits(cid, top - 7 - i * 8, 8) as u8 as char).collect::<String>().trim().into()
+}
+
+// Sectors from a version 1 CSD: MMC and standard capacity SD
+fn csd_v1_sectors(csd: &[u32; 4]) -> u64 {
+    let size = bits(csd, 62, 12) as u64 + 1;
+    let multiplier = bits(csd, 47, 3) + 2;
+    let block_length = bits(csd, 80, 4);
+    (size << (multiplier + block_length)) / SECTOR_SIZE as u64
+}
+
+// Sectors from a version 2 CSD: 512 KiB units
+fn csd_v2_sectors(csd: &[u32; 4]) -> u64 {
+    (bits(csd, 48, 22) as u64 + 1) * 1024
+}
+
+// ACMD41 until the card is powered up, or `None` if it is not SD
+fn sd_op_cond(host: &mut Host, version_2: bool) -> Result<Option<u32>, &'static str> {
+    let argument = OCR_VOLTAGES | if version_2 { OCR_HIGH_CAPACITY } else { 0 };
+    for _ in 0..OP_COND_TRIES {
+        if host.command(APP_CMD, 0, Response::R1, None).is_err() {
+            return Ok(None);
+        }
+        match host.command(SD_SEND_OP_COND, argument, Response::R3, None) {
+            Ok(response) if response[0] & OCR_READY != 0 => return Ok(Some(response[0])),
+            Ok(_) => delay_ms(10),
+            Err(_) => return Ok(None),
+        }
+    }
+    Err("SD card stayed busy")
+}
+
+// CMD1 until the card is powered up
+fn mmc_op_cond(host: &mut Host) -> Result<u32, &'static str> {
+    for _ in 0..OP_COND_TRIES {
+        let response = host.command(SEND_OP_COND, OCR_VOLTAGES | OCR_HIGH_CAPACITY, Response::R3, None)?;
+        if response[0] & OCR_READY != 0 {
+            return Ok(response[0]);
+        }
+        delay_ms(10);
+    }
+    Err("MMC card stayed busy")
+}
+
+fn mmc_switch(host: &mut Host, index: u8, value: u8) -> Result<(), &'static str> {
+    let argument = MMC_SWITCH_WRITE_BYTE | (index as u32) << 16 | (value as u32) << 8;
+    host.command(SWITCH, argument, Response::R1b, None).map(|_| ())
+}
+
+/// Identify the card in a powered slot and bring it to its fastest bus
+pub fn init(host: &mut Host) -> Result<Card, &'static str> {
+    host.set_clock(IDENTIFY_HZ)?;
+    host.set_bus_width(1);
+    host.set_high_speed(false);
+    host.command(GO_IDLE_STATE, 0, Response::None, None)?;
+
+    // SD 2.00 echoes the pattern; older SD cards and MMC stay quiet
+    let version_2 = match host.command(SEND_IF_COND, IF_COND, Response::R1, None) {
+        Ok(response) if response[0] & 0xFFF == IF_COND => true,
+        Ok(_) => return Err("card refused the voltage range"),
+        Err(_) => false,
+    };
+    let (kind, ocr) = match sd_op_cond(host, version_2)? {
+        Some(ocr) if ocr & OCR_HIGH_CAPACITY != 0 => (Kind::Sdhc, ocr),
+        Some(ocr) => (Kind::Sd, ocr),
+        None => {
+            host.command(GO_IDLE_STATE, 0, Response::None, None)?;
+            (Kind::Mmc, mmc_op_cond(host)?)
+        }
+    };
+
+    let cid = host.command(ALL_SEND_CID, 0, Response::R2, None)?;
+    let rca = if kind == Kind::Mmc {
+        host.command(SEND_RELATIVE_ADDR, (MMC_RCA as u32) << 16, Response::R1, None)?;
+        MMC_RCA
+    } else {
+        (host.command(SEND_RELATIVE_ADDR, 0, Response::R1, None)?[0] >> 16) as u16
+    };
+    let csd = host.command(SEND_CSD, (rca as u32) << 16, Response::R2, None)?;
+    host.command(SELECT_CARD, (rca as u32) << 16, Response::R1b, None)?;
+
+    let mut card = Card {
+        kind,
+        rca,
+        high_capacity: match kind {
+            Kind::Mmc => ocr & OCR_ACCESS_MODE == OCR_HIGH_CAPACITY,
+            _ => kind == Kind::Sdhc,
+        },
+        sectors: 0,
+        name: String::new(),
+        serial: 0,
+        bus_width: 1,
+        clock_hz: IDENTIFY_HZ,
+        high_speed: false,
+        read_only: false,
+    };
+    if kind == Kind::Mmc {
+        card.name = cid_name(&cid, 103, 6);
+        card.serial = bits(&cid, 16, 32);
+        setup_mmc(host, &mut card, &csd)?;
+    } else {
+        card.name = cid_name(&cid, 103, 5);
+        card.serial = bits(&cid, 24, 32);
+        card.read_only = host.write_protected();
+        setup_sd(host, &mut card, &csd)?;
+    }
+    Ok(card)
+}
+
+fn setup_sd(host: &mut Host, card: &mut Card, csd: &[u32; 4]) -> Result<(), &'static str> {
+    card.sectors = if bits(csd, 126, 2) == 1 { csd_v2_sectors(csd) } else { csd_v1_sectors(csd) };
+    if !card.high_capacity {
+        host.command(SET_BLOCKLEN, SECTOR_SIZE as u32, Response::R1, None)?;
+    }
+    host.command(APP_CMD, (card.rca as u32) << 16, Response::R1, None)?;
+    host.command(SET_BUS_WIDTH, 2, Response::R1, None)?;
+    host.set_bus_width(4);
+    card.bus_width = 4;
+
+    // The switch status says which function group 1 ended on
+    if host.supports_high_speed() {
+        let mut status = [0u8; 64];
+        let transfer = Transfer { block_size: status.len(), buffer: Buffer::Read(&mut status) };
+        if host.command(SWITCH, SD_SWITCH_HIGH_SPEED, Response::R1, Some(transfer)).is_ok() && status[16] & 0xF == 1 {
+            host.set_high_speed(true);
+            card.high_speed = true;
+        }
+    }
+    card.clock_hz = host.set_clock(if card.high_speed { SD_HIGH_SPEED_HZ } else { SD_DEFAULT_HZ })?;
+    Ok(())
+}
+
+fn setup_mmc(host: &mut Host, card: &mut Card, csd: &[u32; 4]) -> Result<(), &'static str> {
+    card.sectors = csd_v1_sectors(csd);
+    // EXT_CSD came with version 4 of the specification
+    if bits(csd, 122, 4) >= 4 {
+        let mut ext_csd = vec![0u8; 512];
+        let transfer = Transfer { block_size: ext_csd.len(), buffer: Buffer::Read(&mut ext_csd) };
+        host.command(SEND_EXT_CSD, 0, Response::R1, Some(transfer))?;
+        if card.high_capacity {
+            let count = &ext_csd[EXT_CSD_SEC_COUNT..EXT_CSD_SEC_COUNT + 4];
+            card.sectors = u32::from_le_bytes([count[0], count[1], count[2], count[3]]) as u64;
+        }
+        // EXT_CSD bus width: 1 is 4 bits, 2 is 8
+        let (width, value) = if host.supports_8bit() { (8, 2) } else { (4, 1) };
+        mmc_switch(host, EXT_CSD_BUS_WIDTH, value)?;
+        host.set_bus_width(width);
+        card.bus_width = width;
+        if ext_csd[EXT_CSD_CARD_TYPE] & CARD_TYPE_HS_52 != 0 && host.supports_high_speed() {
+            mmc_switch(host, EXT_CSD_HS_TIMING, 1)?;
+            host.set_high_speed(true);
+            card.high_speed = true;
+        }
+    }
+    card.clock_hz = host.set_clock(if card.high_speed { MMC_HIGH_SPEED_HZ } else { MMC_DEFAULT_HZ })?;
+    Ok(())
+}
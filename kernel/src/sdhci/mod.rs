@@ -0,0 +1,842 @@
+//! SD Host Controller Interface: SD cards and eMMC
+//!
+//! Controllers are found on PCI (class 08h, subclass 05h), with one to six
+//! slots each behind consecutive BARs. Each slot is polled for a card
+//! being put in or taken out; a card found is brought up by `card` and
+//! becomes a disk named `mmcblk0`, `mmcblk1` and so on after the slot, which
+//! keeps its name while the slot is empty.
+//!
+//! Data moves by ADMA2 through a bounce buffer below 4 GiB, or through the
+//! data port on controllers without ADMA2. Interrupts stay off and every
+//! command is waited on, as the NVMe and AHCI drivers do.
+
+pub mod card;
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::drivers::disk::{DiskDriver, DiskError, DiskInfo, DISK_MANAGER};
+use crate::memory::frame_allocator::FRAME_ALLOCATOR;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PciDevice, PciLocation, PCIE_CONTROLLER, PCI_CLASS_SYSTEM};
+use crate::time::clocksource::now_ns;
+
+use card::Card;
+
+pub const PCI_SUBCLASS_SD_HOST: u8 = 0x05;
+// PCI configuration: the first slot's BAR and the number of slots
+const PCI_SLOT_INFO: u8 = 0x40;
+
+// Registers
+const REG_BLOCK_SIZE: usize = 0x04;
+const REG_BLOCK_COUNT: usize = 0x06;
+const REG_ARGUMENT: usize = 0x08;
+const REG_TRANSFER_MODE: usize = 0x0C;
+const REG_COMMAND: usize = 0x0E;
+const REG_RESPONSE: usize = 0x10;
+const REG_DATA_PORT: usize = 0x20;
+const REG_PRESENT_STATE: usize = 0x24;
+const REG_HOST_CONTROL: usize = 0x28;
+const REG_POWER_CONTROL: usize = 0x29;
+const REG_CLOCK_CONTROL: usize = 0x2C;
+const REG_TIMEOUT_CONTROL: usize = 0x2E;
+const REG_SOFTWARE_RESET: usize = 0x2F;
+const REG_NORMAL_STATUS: usize = 0x30;
+const REG_ERROR_STATUS: usize = 0x32;
+const REG_NORMAL_STATUS_ENABLE: usize = 0x34;
+const REG_ERROR_STATUS_ENABLE: usize = 0x36;
+const REG_NORMAL_SIGNAL_ENABLE: usize = 0x38;
+const REG_ERROR_SIGNAL_ENABLE: usize = 0x3A;
+const REG_CAPABILITIES: usize = 0x40;
+const REG_ADMA_ADDRESS: usize = 0x58;
+const REG_HOST_VERSION: usize = 0xFE;
+
+// Transfer mode
+const TM_DMA: u16 = 1 << 0;
+const TM_BLOCK_COUNT: u16 = 1 << 1;
+const TM_AUTO_CMD12: u16 = 1 << 2;
+const TM_READ: u16 = 1 << 4;
+const TM_MULTI_BLOCK: u16 = 1 << 5;
+
+// Command register: response type, checks, and whether data follows
+const CMD_RESPONSE_136: u16 = 1;
+const CMD_RESPONSE_48: u16 = 2;
+const CMD_RESPONSE_48_BUSY: u16 = 3;
+const CMD_CRC_CHECK: u16 = 1 << 3;
+const CMD_INDEX_CHECK: u16 = 1 << 4;
+const CMD_DATA_PRESENT: u16 = 1 << 5;
+
+// Present state
+const PS_CMD_INHIBIT: u32 = 1 << 0;
+const PS_DAT_INHIBIT: u32 = 1 << 1;
+const PS_CARD_INSERTED: u32 = 1 << 16;
+const PS_WRITE_ENABLED: u32 = 1 << 19;
+
+// Host control 1
+const HC_4BIT: u8 = 1 << 1;
+const HC_HIGH_SPEED: u8 = 1 << 2;
+const HC_ADMA2: u8 = 2 << 3;
+const HC_DMA_MASK: u8 = 3 << 3;
+const HC_8BIT: u8 = 1 << 5;
+
+// Power control: bus voltage, then power on
+const POWER_ON: u8 = 1 << 0;
+const POWER_180: u8 = 5 << 1;
+const POWER_300: u8 = 6 << 1;
+const POWER_330: u8 = 7 << 1;
+
+// Clock control
+const CLOCK_INTERNAL_ENABLE: u16 = 1 << 0;
+const CLOCK_INTERNAL_STABLE: u16 = 1 << 1;
+const CLOCK_CARD_ENABLE: u16 = 1 << 2;
+
+// Software reset
+const RESET_ALL: u8 = 1 << 0;
+const RESET_CMD: u8 = 1 << 1;
+const RESET_DATA: u8 = 1 << 2;
+
+// Normal interrupt status
+const INT_COMMAND_COMPLETE: u16 = 1 << 0;
+const INT_TRANSFER_COMPLETE: u16 = 1 << 1;
+const INT_BUFFER_WRITE_READY: u16 = 1 << 4;
+const INT_BUFFER_READ_READY: u16 = 1 << 5;
+const INT_CARD_INSERTION: u16 = 1 << 6;
+const INT_CARD_REMOVAL: u16 = 1 << 7;
+const INT_ERROR: u16 = 1 << 15;
+
+// Error interrupt status
+const ERR_COMMAND_TIMEOUT: u16 = 1 << 0;
+const ERR_COMMAND_CRC: u16 = 1 << 1;
+const ERR_DATA_TIMEOUT: u16 = 1 << 4;
+const ERR_DATA_CRC: u16 = 1 << 5;
+const ERR_ADMA: u16 = 1 << 9;
+
+// Capabilities
+const CAP_8BIT: u64 = 1 << 18;
+const CAP_ADMA2: u64 = 1 << 19;
+const CAP_HIGH_SPEED: u64 = 1 << 21;
+const CAP_330: u64 = 1 << 24;
+const CAP_300: u64 = 1 << 25;
+const CAP_180: u64 = 1 << 26;
+
+// ADMA2 descriptor attributes: valid, last, and transfer data
+const ADMA_VALID: u16 = 1 << 0;
+const ADMA_END: u16 = 1 << 1;
+const ADMA_TRANSFER: u16 = 2 << 4;
+// Bytes one descriptor moves; a page keeps each inside a frame
+const ADMA_CHUNK: usize = 4096;
+
+/// Frames in a slot's bounce buffer, and so the most one command moves
+const BOUNCE_FRAMES: usize = 16;
+pub const MAX_TRANSFER: usize = BOUNCE_FRAMES * 4096;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const COMMAND_TIMEOUT_MS: u64 = 100;
+const DATA_TIMEOUT_MS: u64 = 2000;
+const RESET_TIMEOUT_MS: u64 = 100;
+
+// Slots are checked for cards at this interval
+const POLL_INTERVAL_NS: u64 = 100_000_000;
+
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+/// What follows a command on the response line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    None,
+    /// 136 bits: a CID or CSD
+    R2,
+    /// 48 bits with CRC and index, as R1, R6 and R7 are
+    R1,
+    /// R1, then busy on DAT0 until the card is done
+    R1b,
+    /// 48 bits without CRC or index: an OCR
+    R3,
+}
+
+impl Response {
+    fn flags(self) -> u16 {
+        match self {
+            Response::None => 0,
+            Response::R2 => CMD_RESPONSE_136 | CMD_CRC_CHECK,
+            Response::R1 => CMD_RESPONSE_48 | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+            Response::R1b => CMD_RESPONSE_48_BUSY | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+            Response::R3 => CMD_RESPONSE_48,
+        }
+    }
+}
+
+/// Where a command's data goes or comes from
+pub enum Buffer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// A command's data phase: whole blocks of `block_size` bytes
+pub struct Transfer<'a> {
+    pub block_size: usize,
+    pub buffer: Buffer<'a>,
+}
+
+impl Transfer<'_> {
+    fn len(&self) -> usize {
+        match &self.buffer {
+            Buffer::Read(data) => data.len(),
+            Buffer::Write(data) => data.len(),
+        }
+    }
+}
+
+// The ADMA2 descriptor table and the buffer it points at, both below 4 GiB
+#[derive(Debug, Clone, Copy)]
+struct Dma {
+    table: u64,
+    buffer: u64,
+}
+
+impl Dma {
+    fn allocate() -> Option<Dma> {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let first = allocator.allocate_contiguous(BOUNCE_FRAMES + 1, None)?;
+        let table = first.start_address().as_u64();
+        if table + ((BOUNCE_FRAMES + 1) * 4096) as u64 > u32::MAX as u64 {
+            // 32-bit descriptors cannot reach it; use the data port instead
+            for frame in PhysFrame::range(first, first + (BOUNCE_FRAMES + 1) as u64) {
+                allocator.deallocate_frame(frame);
+            }
+            return None;
+        }
+        Some(Dma { table, buffer: table + 4096 })
+    }
+
+    // Descriptors covering the first `length` bytes of the buffer
+    fn describe(&self, length: usize) {
+        let table = (PHYS_MEM_OFFSET + self.table) as *mut u64;
+        let count = length.div_ceil(ADMA_CHUNK);
+        for index in 0..count {
+            let size = (length - index * ADMA_CHUNK).min(ADMA_CHUNK);
+            let mut attributes = ADMA_VALID | ADMA_TRANSFER;
+            if index + 1 == count {
+                attributes |= ADMA_END;
+            }
+            // Length 0 means 64 KiB, which a page never is
+            let address = self.buffer + (index * ADMA_CHUNK) as u64;
+            let descriptor = attributes as u64 | (size as u64) << 16 | address << 32;
+            unsafe { write_volatile(table.add(index), descriptor) };
+        }
+    }
+
+    fn bytes(&self) -> *mut u8 {
+        (PHYS_MEM_OFFSET + self.buffer) as *mut u8
+    }
+}
+
+// Spin until `done` says so or `timeout_ms` passes, saying which
+fn wait(timeout_ms: u64, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = now_ns() + timeout_ms * 1_000_000;
+    loop {
+        if done() {
+            return true;
+        }
+        if now_ns() >= deadline {
+            return done();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+pub fn delay_ms(ms: u64) {
+    wait(ms, || false);
+}
+
+/// One slot's registers
+pub struct Host {
+    pub base: u64,
+    /// Specification version less one: 2 is SDHCI 3.00
+    pub version: u8,
+    pub capabilities: u64,
+    /// The clock cards are divided down from
+    pub base_clock: u32,
+    dma: Option<Dma>,
+}
+
+impl Host {
+    fn read8(&self, register: usize) -> u8 {
+        unsafe { read_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *const u8) }
+    }
+
+    fn read16(&self, register: usize) -> u16 {
+        unsafe { read_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *const u16) }
+    }
+
+    fn read32(&self, register: usize) -> u32 {
+        unsafe { read_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *const u32) }
+    }
+
+    fn write8(&self, register: usize, value: u8) {
+        unsafe { write_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *mut u8, value) }
+    }
+
+    fn write16(&self, register: usize, value: u16) {
+        unsafe { write_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *mut u16, value) }
+    }
+
+    fn write32(&self, register: usize, value: u32) {
+        unsafe { write_volatile((PHYS_MEM_OFFSET + self.base + register as u64) as *mut u32, value) }
+    }
+
+    /// Reset the slot at `base` and turn its status bits on
+    pub fn new(base: u64) -> Result<Host, &'static str> {
+        let mut host = Host { base, version: 0, capabilities: 0, base_clock: 0, dma: None };
+        host.version = host.read16(REG_HOST_VERSION) as u8;
+        let capabilities = host.read32(REG_CAPABILITIES) as u64 | (host.read32(REG_CAPABILITIES + 4) as u64) << 32;
+        if capabilities as u32 == u32::MAX {
+            return Err("slot registers read as all ones");
+        }
+        host.capabilities = capabilities;
+        // Base clock in MHz: six bits before 3.00, eight from it on
+        let mask = if host.version >= 2 { 0xFF } else { 0x3F };
+        host.base_clock = ((capabilities >> 8) & mask) as u32 * 1_000_000;
+        if host.base_clock == 0 {
+            return Err("no base clock given");
+        }
+        host.reset(RESET_ALL)?;
+        host.write16(REG_NORMAL_STATUS_ENABLE, 0xFFFF);
+        host.write16(REG_ERROR_STATUS_ENABLE, 0xFFFF);
+        host.write16(REG_NORMAL_SIGNAL_ENABLE, 0);
+        host.write16(REG_ERROR_SIGNAL_ENABLE, 0);
+        host.write8(REG_TIMEOUT_CONTROL, 0x0E);
+        if capabilities & CAP_ADMA2 != 0 {
+            host.dma = Dma::allocate();
+        }
+        Ok(host)
+    }
+
+    fn reset(&self, mask: u8) -> Result<(), &'static str> {
+        self.write8(REG_SOFTWARE_RESET, mask);
+        if wait(RESET_TIMEOUT_MS, || self.read8(REG_SOFTWARE_RESET) & mask == 0) {
+            Ok(())
+        } else {
+            Err("controller reset timed out")
+        }
+    }
+
+    pub fn card_present(&self) -> bool {
+        self.read32(REG_PRESENT_STATE) & PS_CARD_INSERTED != 0
+    }
+
+    pub fn write_protected(&self) -> bool {
+        self.read32(REG_PRESENT_STATE) & PS_WRITE_ENABLED == 0
+    }
+
+    pub fn uses_dma(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    pub fn supports_8bit(&self) -> bool {
+        self.capabilities & CAP_8BIT != 0
+    }
+
+    pub fn supports_high_speed(&self) -> bool {
+        self.capabilities & CAP_HIGH_SPEED != 0
+    }
+
+    /// Power the card at the highest voltage the slot offers
+    pub fn power_on(&self) -> Result<(), &'static str> {
+        let voltage = if self.capabilities & CAP_330 != 0 {
+            POWER_330
+        } else if self.capabilities & CAP_300 != 0 {
+            POWER_300
+        } else if self.capabilities & CAP_180 != 0 {
+            POWER_180
+        } else {
+            return Err("no supported voltage");
+        };
+        self.write8(REG_POWER_CONTROL, voltage);
+        self.write8(REG_POWER_CONTROL, voltage | POWER_ON);
+        // Cards want a millisecond and 74 clocks before the first command
+        delay_ms(2);
+        Ok(())
+    }
+
+    pub fn power_off(&self) {
+        self.write16(REG_CLOCK_CONTROL, 0);
+        self.write8(REG_POWER_CONTROL, 0);
+    }
+
+    /// Run the card clock as close to `hz` as the divider allows without
+    /// going over, returning what it runs at
+    pub fn set_clock(&self, hz: u32) -> Result<u32, &'static str> {
+        self.write16(REG_CLOCK_CONTROL, 0);
+        // 3.00 divides by any even number up to 2046; before it, by
+        // powers of two up to 256
+        let (divisor, field) = if self.base_clock <= hz {
+            (1, 0)
+        } else if self.version >= 2 {
+            let divisor = self.base_clock.div_ceil(hz).div_ceil(2).min(1023);
+            (divisor * 2, divisor)
+        } else {
+            let mut divisor = 2;
+            while divisor < 256 && self.base_clock / divisor > hz {
+                divisor *= 2;
+            }
+            (divisor, divisor / 2)
+        };
+        let field = ((field & 0xFF) << 8 | ((field >> 8) & 0x3) << 6) as u16;
+        self.write16(REG_CLOCK_CONTROL, field | CLOCK_INTERNAL_ENABLE);
+        if !wait(RESET_TIMEOUT_MS, || self.read16(REG_CLOCK_CONTROL) & CLOCK_INTERNAL_STABLE != 0) {
+            return Err("clock did not settle");
+        }
+        self.write16(REG_CLOCK_CONTROL, field | CLOCK_INTERNAL_ENABLE | CLOCK_CARD_ENABLE);
+        Ok(self.base_clock / divisor)
+    }
+
+    /// Data bus width: 1, 4 or 8 bits
+    pub fn set_bus_width(&self, width: u8) {
+        let mut control = self.read8(REG_HOST_CONTROL) & !(HC_4BIT | HC_8BIT);
+        match width {
+            8 => control |= HC_8BIT,
+            4 => control |= HC_4BIT,
+            _ => {}
+        }
+        self.write8(REG_HOST_CONTROL, control);
+    }
+
+    pub fn set_high_speed(&self, enabled: bool) {
+        let control = self.read8(REG_HOST_CONTROL) & !HC_HIGH_SPEED;
+        self.write8(REG_HOST_CONTROL, control | if enabled { HC_HIGH_SPEED } else { 0 });
+    }
+
+    // Wait for any of `mask` in the normal status, or an error
+    fn wait_status(&self, mask: u16, timeout_ms: u64) -> Result<(), &'static str> {
+        let mut status = 0;
+        let finished = wait(timeout_ms, || {
+            status = self.read16(REG_NORMAL_STATUS);
+            status & (mask | INT_ERROR) != 0
+        });
+        if status & INT_ERROR != 0 {
+            let errors = self.read16(REG_ERROR_STATUS);
+            self.write16(REG_ERROR_STATUS, errors);
+            let _ = self.reset(RESET_CMD | RESET_DATA);
+            return Err(if errors & ERR_COMMAND_TIMEOUT != 0 {
+                "command timed out"
+            } else if errors & ERR_COMMAND_CRC != 0 {
+                "command CRC error"
+            } else if errors & ERR_DATA_TIMEOUT != 0 {
+                "data timed out"
+            } else if errors & ERR_DATA_CRC != 0 {
+                "data CRC error"
+            } else if errors & ERR_ADMA != 0 {
+                "ADMA error"
+            } else {
+                "command failed"
+            });
+        }
+        if !finished {
+            let _ = self.reset(RESET_CMD | RESET_DATA);
+            return Err("controller did not answer");
+        }
+        self.write16(REG_NORMAL_STATUS, status & mask);
+        Ok(())
+    }
+
+    /// Send command `index` and wait for its response and any data, which
+    /// is at most `MAX_TRANSFER` bytes. Responses come as the controller
+    /// holds them: R2 without its CRC byte, the others in the first word.
+    pub fn command(
+        &mut self,
+        index: u8,
+        argument: u32,
+        response: Response,
+        mut data: Option<Transfer>,
+    ) -> Result<[u32; 4], &'static str> {
+        let busy = response == Response::R1b || data.is_some();
+        let inhibit = PS_CMD_INHIBIT | if busy { PS_DAT_INHIBIT } else { 0 };
+        if !wait(COMMAND_TIMEOUT_MS, || self.read32(REG_PRESENT_STATE) & inhibit == 0) {
+            let _ = self.reset(RESET_CMD | RESET_DATA);
+            return Err("controller busy");
+        }
+        self.write16(REG_NORMAL_STATUS, 0xFFFF & !(INT_CARD_INSERTION | INT_CARD_REMOVAL));
+        self.write16(REG_ERROR_STATUS, 0xFFFF);
+
+        let mut mode = 0;
+        let mut flags = response.flags() | (index as u16) << 8;
+        if let Some(transfer) = &data {
+            let length = transfer.len();
+            if length == 0 || length > MAX_TRANSFER || length % transfer.block_size != 0 {
+                return Err("bad transfer length");
+            }
+            let blocks = length / transfer.block_size;
+            self.write16(REG_BLOCK_SIZE, transfer.block_size as u16);
+            self.write16(REG_BLOCK_COUNT, blocks as u16);
+            mode = TM_BLOCK_COUNT;
+            if blocks > 1 {
+                mode |= TM_MULTI_BLOCK | TM_AUTO_CMD12;
+            }
+            if let Buffer::Read(_) = transfer.buffer {
+                mode |= TM_READ;
+            }
+            if let Some(dma) = self.dma {
+                if let Buffer::Write(bytes) = transfer.buffer {
+                    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), dma.bytes(), length) };
+                }
+                dma.describe(length);
+                self.write32(REG_ADMA_ADDRESS, dma.table as u32);
+                let control = self.read8(REG_HOST_CONTROL) & !HC_DMA_MASK;
+                self.write8(REG_HOST_CONTROL, control | HC_ADMA2);
+                mode |= TM_DMA;
+            }
+            flags |= CMD_DATA_PRESENT;
+        }
+        self.write32(REG_ARGUMENT, argument);
+        self.write16(REG_TRANSFER_MODE, mode);
+        self.write16(REG_COMMAND, flags);
+
+        self.wait_status(INT_COMMAND_COMPLETE, COMMAND_TIMEOUT_MS)?;
+        let mut words = [0u32; 4];
+        for (offset, word) in words.iter_mut().enumerate() {
+            *word = self.read32(REG_RESPONSE + offset * 4);
+        }
+
+        match data.as_mut() {
+            Some(transfer) => self.transfer(transfer)?,
+            None if response == Response::R1b => self.wait_status(INT_TRANSFER_COMPLETE, DATA_TIMEOUT_MS)?,
+            None => {}
+        }
+        Ok(words)
+    }
+
+    // The data phase of a command
+    fn transfer(&self, transfer: &mut Transfer) -> Result<(), &'static str> {
+        if let Some(dma) = self.dma {
+            self.wait_status(INT_TRANSFER_COMPLETE, DATA_TIMEOUT_MS)?;
+            if let Buffer::Read(bytes) = &mut transfer.buffer {
+                unsafe { core::ptr::copy_nonoverlapping(dma.bytes(), bytes.as_mut_ptr(), bytes.len()) };
+            }
+            return Ok(());
+        }
+        let block_size = transfer.block_size;
+        match &mut transfer.buffer {
+            Buffer::Read(bytes) => {
+                for block in bytes.chunks_mut(block_size) {
+                    self.wait_status(INT_BUFFER_READ_READY, DATA_TIMEOUT_MS)?;
+                    for word in block.chunks_mut(4) {
+                        let value = self.read32(REG_DATA_PORT).to_le_bytes();
+                        word.copy_from_slice(&value[..word.len()]);
+                    }
+                }
+            }
+            Buffer::Write(bytes) => {
+                for block in bytes.chunks(block_size) {
+                    self.wait_status(INT_BUFFER_WRITE_READY, DATA_TIMEOUT_MS)?;
+                    for word in block.chunks(4) {
+                        let mut value = [0u8; 4];
+                        value[..word.len()].copy_from_slice(word);
+                        self.write32(REG_DATA_PORT, u32::from_le_bytes(value));
+                    }
+                }
+            }
+        }
+        self.wait_status(INT_TRANSFER_COMPLETE, DATA_TIMEOUT_MS)
+    }
+
+    // Insertion and removal seen since last asked
+    fn take_card_events(&self) -> u16 {
+        let events = self.read16(REG_NORMAL_STATUS) & (INT_CARD_INSERTION | INT_CARD_REMOVAL);
+        self.write16(REG_NORMAL_STATUS, events);
+        events
+    }
+}
+
+/// One slot of a controller, and the card in it
+pub struct Slot {
+    pub device: DeviceId,
+    /// The controller's PCI address
+    pub controller: String,
+    pub number: usize,
+    pub host: Host,
+    pub card: Option<Card>,
+    /// The disk this slot was registered as, kept while it is empty
+    pub disk: Option<usize>,
+    /// The controller has gone away
+    pub removed: bool,
+    // A card is in but could not be brought up; wait until it is swapped
+    failed: bool,
+}
+
+impl Slot {
+    fn read(&mut self, start: u64, count: u32, buffer: &mut [u8]) -> Result<(), &'static str> {
+        let card = self.card.as_ref().ok_or("no card")?;
+        let per_command = MAX_TRANSFER / SECTOR_SIZE;
+        let mut sector = start;
+        for chunk in buffer[..count as usize * SECTOR_SIZE].chunks_mut(per_command * SECTOR_SIZE) {
+            let blocks = chunk.len() / SECTOR_SIZE;
+            let index = if blocks > 1 { card::READ_MULTIPLE_BLOCK } else { card::READ_SINGLE_BLOCK };
+            let transfer = Transfer { block_size: SECTOR_SIZE, buffer: Buffer::Read(chunk) };
+            self.host.command(index, card.address(sector), Response::R1, Some(transfer))?;
+            sector += blocks as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, start: u64, count: u32, data: &[u8]) -> Result<(), &'static str> {
+        let card = self.card.as_ref().ok_or("no card")?;
+        if card.read_only {
+            return Err("card is write-protected");
+        }
+        let per_command = MAX_TRANSFER / SECTOR_SIZE;
+        let mut sector = start;
+        for chunk in data[..count as usize * SECTOR_SIZE].chunks(per_command * SECTOR_SIZE) {
+            let blocks = chunk.len() / SECTOR_SIZE;
+            let index = if blocks > 1 { card::WRITE_MULTIPLE_BLOCK } else { card::WRITE_BLOCK };
+            let transfer = Transfer { block_size: SECTOR_SIZE, buffer: Buffer::Write(chunk) };
+            self.host.command(index, card.address(sector), Response::R1, Some(transfer))?;
+            sector += blocks as u64;
+        }
+        Ok(())
+    }
+
+    // Bring up a card just found, or let one go
+    fn check(&mut self) {
+        if self.removed {
+            return;
+        }
+        let events = self.host.take_card_events();
+        let present = self.host.card_present();
+        if self.card.is_some() && (!present || events & INT_CARD_REMOVAL != 0) {
+            self.card = None;
+            self.host.power_off();
+            crate::serial_println!("sdhci: card removed from slot {} of {}", self.number, self.controller);
+        }
+        if !present {
+            self.failed = false;
+            return;
+        }
+        if self.card.is_some() || self.failed {
+            return;
+        }
+        match self.host.power_on().and_then(|_| card::init(&mut self.host)) {
+            Ok(card) => {
+                crate::serial_println!(
+                    "sdhci: {} card {:?} in slot {} of {}, {} MiB, {}-bit at {} kHz, {}",
+                    card.kind.name(),
+                    card.name,
+                    self.number,
+                    self.controller,
+                    card.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+                    card.bus_width,
+                    card.clock_hz / 1000,
+                    if self.host.uses_dma() { "ADMA2" } else { "PIO" }
+                );
+                self.card = Some(card);
+            }
+            Err(e) => {
+                crate::serial_println!(
+                    "sdhci: card in slot {} of {} not brought up: {}",
+                    self.number,
+                    self.controller,
+                    e
+                );
+                self.host.power_off();
+                self.failed = true;
+            }
+        }
+    }
+}
+
+static SLOTS: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+
+/// A slot as a disk: empty slots answer `NotFound`
+pub struct SdDisk {
+    slot: usize,
+}
+
+impl DiskDriver for SdDisk {
+    fn read_sectors(&mut self, start_sector: u64, count: u32, buffer: &mut [u8]) -> Result<(), DiskError> {
+        if buffer.len() < count as usize * SECTOR_SIZE {
+            return Err(DiskError::BufferTooSmall);
+        }
+        let mut slots = SLOTS.lock();
+        let slot = slots.get_mut(self.slot).filter(|slot| slot.card.is_some()).ok_or(DiskError::NotFound)?;
+        if start_sector + count as u64 > slot.card.as_ref().map_or(0, |card| card.sectors) {
+            return Err(DiskError::InvalidSector);
+        }
+        slot.read(start_sector, count, buffer).map_err(|e| {
+            crate::serial_println!("sdhci: read of sector {} failed: {}", start_sector, e);
+            DiskError::IoError
+        })
+    }
+
+    fn write_sectors(&mut self, start_sector: u64, count: u32, data: &[u8]) -> Result<(), DiskError> {
+        if data.len() < count as usize * SECTOR_SIZE {
+            return Err(DiskError::BufferTooSmall);
+        }
+        let mut slots = SLOTS.lock();
+        let slot = slots.get_mut(self.slot).filter(|slot| slot.card.is_some()).ok_or(DiskError::NotFound)?;
+        if start_sector + count as u64 > slot.card.as_ref().map_or(0, |card| card.sectors) {
+            return Err(DiskError::InvalidSector);
+        }
+        slot.write(start_sector, count, data).map_err(|e| {
+            crate::serial_println!("sdhci: write of sector {} failed: {}", start_sector, e);
+            DiskError::IoError
+        })
+    }
+
+    fn get_info(&self) -> DiskInfo {
+        let slots = SLOTS.lock();
+        let card = slots.get(self.slot).and_then(|slot| slot.card.as_ref());
+        DiskInfo {
+            name: format!("mmcblk{}", self.slot),
+            sectors: card.map_or(0, |card| card.sectors),
+            sector_size: SECTOR_SIZE,
+            model: card.map_or_else(String::new, |card| card.name.clone()),
+            serial: card.map_or_else(String::new, |card| format!("{:08x}", card.serial)),
+        }
+    }
+}
+
+// Check every slot, and give those with a first card a disk. Disks are
+// added with the slot list unlocked, as reads through the disk manager
+// take it the other way round.
+fn scan() {
+    let fresh: Vec<usize> = {
+        let mut slots = SLOTS.lock();
+        for slot in slots.iter_mut() {
+            slot.check();
+        }
+        slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.card.is_some() && slot.disk.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    };
+    for index in fresh {
+        let disk = DISK_MANAGER.lock().add_disk(Box::new(SdDisk { slot: index }));
+        if let Some(slot) = SLOTS.lock().get_mut(index) {
+            slot.disk = Some(disk);
+        }
+    }
+}
+
+/// Notice cards put in and taken out; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+    if !SLOTS.lock().is_empty() {
+        scan();
+    }
+}
+
+/// The slots found so far, with the card in each
+pub fn slots() -> Vec<(usize, String, usize, Option<Card>)> {
+    SLOTS
+        .lock()
+        .iter()
+        .enumerate()
+        .filter(|(_, slot)| !slot.removed)
+        .map(|(index, slot)| (index, slot.controller.clone(), slot.number, slot.card.clone()))
+        .collect()
+}
+
+// A memory BAR's address, 64-bit ones taking the next BAR too
+fn bar_address(device: &PciDevice, bar: usize) -> Option<u64> {
+    let low = *device.bars.get(bar)?;
+    if low & 1 != 0 {
+        return None;
+    }
+    let mut base = (low & !0xF) as u64;
+    if (low >> 1) & 3 == 2 {
+        base |= (*device.bars.get(bar + 1)? as u64) << 32;
+    }
+    if base == 0 { None } else { Some(base) }
+}
+
+fn pci_function(location: PciLocation) -> Option<PciDevice> {
+    PCIE_CONTROLLER.lock().devices().iter().find(|d| d.location == location).cloned()
+}
+
+/// Binds SD host controllers found on PCI
+pub static PCI_DRIVER: SdhciPciDriver = SdhciPciDriver;
+
+pub struct SdhciPciDriver;
+
+impl Driver for SdhciPciDriver {
+    fn name(&self) -> &'static str {
+        "sdhci"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Pci
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[Match::PciClass { class: PCI_CLASS_SYSTEM, subclass: PCI_SUBCLASS_SD_HOST, prog_if: None }]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Pci { location, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let pci = pci_function(location).ok_or(ProbeError::NoDevice)?;
+        PCIE_CONTROLLER.lock().enable_device(&pci);
+        let info = PCIE_CONTROLLER.lock().read8(location, PCI_SLOT_INFO);
+        let first_bar = (info & 0x7) as usize;
+        let count = ((info >> 4) & 0x7) as usize + 1;
+
+        let mut found = 0;
+        for number in 0..count {
+            let Some(base) = bar_address(&pci, first_bar + number) else {
+                continue;
+            };
+            match Host::new(base) {
+                Ok(host) => {
+                    crate::serial_println!(
+                        "sdhci: slot {} of {} at 0x{:x}, version {}.00{}",
+                        number,
+                        device.name,
+                        base,
+                        host.version + 1,
+                        if host.uses_dma() { ", ADMA2" } else { "" }
+                    );
+                    SLOTS.lock().push(Slot {
+                        device: device.id,
+                        controller: device.name.clone(),
+                        number,
+                        host,
+                        card: None,
+                        disk: None,
+                        removed: false,
+                        failed: false,
+                    });
+                    found += 1;
+                }
+                Err(e) => crate::serial_println!("sdhci: slot {} of {}: {}", number, device.name, e),
+            }
+        }
+        if found == 0 {
+            return Err(ProbeError::Failed("no usable slots"));
+        }
+        scan();
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        for slot in SLOTS.lock().iter_mut().filter(|slot| slot.device == device.id) {
+            slot.card = None;
+            slot.removed = true;
+        }
+    }
+}
@@ -0,0 +1,504 @@
+// Local user accounts and logon
+//
+// Accounts live in the SAM hive (HKLM\SAM\SAM\Domains\Account), which only
+// the SYSTEM account may read through the registry API.  Passwords are stored
+// as salted PBKDF2-HMAC-SHA256 hashes.  A successful logon produces an NT
+// primary token carrying the user's SID, group SIDs and privileges; the shell
+// attaches it to its process so later object and file access is checked
+// against that user rather than the implicit SYSTEM identity.
+
+use crate::crypto::errors::{CryptoError, CryptoResult};
+use crate::crypto::hash::SHA256;
+use crate::crypto::mac::{Hmac, Mac};
+use crate::crypto::rng::{HardwareRng, RandomSource};
+use crate::nt::object::Handle;
+use crate::nt::security::{
+    LuidAndAttributes, Luid, Privilege, PrivilegeAttributes, Sid, TokenType, WellKnownSids,
+    SECURITY_MANAGER, SE_GROUP_ENABLED, SE_GROUP_ENABLED_BY_DEFAULT, SE_GROUP_MANDATORY,
+};
+use crate::nt::NtStatus;
+use crate::nt::registry::{RegistryKey, RegistryValue, REGISTRY_MANAGER};
+use super::audit::{self, EventDetails, SecurityEvent, Severity};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::sync::Mutex;
+use lazy_static::lazy_static;
+
+const SAM_ACCOUNT_PATH: &str = "HKLM\\SAM\\SAM\\Domains\\Account";
+const SAM_USERS_PATH: &str = "HKLM\\SAM\\SAM\\Domains\\Account\\Users";
+
+pub const DOMAIN_USER_RID_ADMIN: u32 = 500;
+pub const DOMAIN_USER_RID_GUEST: u32 = 501;
+const FIRST_USER_RID: u32 = 1000;
+
+const PBKDF2_ITERATIONS: u32 = 4096;
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+
+const ACCOUNT_DISABLED: u32 = 0x0001;
+const ACCOUNT_ADMIN: u32 = 0x0002;
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub rid: u32,
+    pub flags: u32,
+    salt: Vec<u8>,
+    password_hash: Vec<u8>,
+    iterations: u32,
+}
+
+impl Account {
+    pub fn is_admin(&self) -> bool {
+        self.flags & ACCOUNT_ADMIN != 0
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.flags & ACCOUNT_DISABLED != 0
+    }
+
+    pub fn has_password(&self) -> bool {
+        !self.password_hash.is_empty()
+    }
+
+    fn set_password(&mut self, password: &str) -> Result<(), NtStatus> {
+        let salt = HardwareRng::new().get_entropy(SALT_LENGTH);
+        self.password_hash = pbkdf2_sha256(password.as_bytes(), &salt, PBKDF2_ITERATIONS, HASH_LENGTH)
+            .map_err(|_| NtStatus::InternalError)?;
+        self.salt = salt;
+        self.iterations = PBKDF2_ITERATIONS;
+        Ok(())
+    }
+
+    fn verify_password(&self, password: &str) -> bool {
+        if !self.has_password() {
+            return password.is_empty();
+        }
+        match pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations, self.password_hash.len()) {
+            Ok(candidate) => constant_time_eq(&candidate, &self.password_hash),
+            Err(_) => false,
+        }
+    }
+}
+
+pub struct AccountDatabase {
+    accounts: BTreeMap<String, Account>,
+    machine_sid: [u32; 3],
+    next_rid: u32,
+}
+
+lazy_static! {
+    pub static ref ACCOUNTS: Mutex<AccountDatabase> = Mutex::new(AccountDatabase::new());
+}
+
+impl AccountDatabase {
+    pub fn new() -> Self {
+        Self {
+            accounts: BTreeMap::new(),
+            machine_sid: [0; 3],
+            next_rid: FIRST_USER_RID,
+        }
+    }
+
+    /// Load accounts from the SAM hive, creating the built-in accounts and
+    /// machine SID on first boot
+    pub fn load(&mut self) {
+        let mut registry = REGISTRY_MANAGER.lock();
+        let Some(domain) = registry.create_key_by_path(SAM_ACCOUNT_PATH) else {
+            return;
+        };
+
+        match domain.get_value("MachineSid").and_then(RegistryValue::as_binary) {
+            Some(bytes) if bytes.len() == 12 => {
+                for (i, chunk) in bytes.chunks_exact(4).enumerate() {
+                    self.machine_sid[i] = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+            _ => {
+                let entropy = HardwareRng::new().get_entropy(12);
+                for (i, chunk) in entropy.chunks_exact(4).enumerate() {
+                    self.machine_sid[i] = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+                let bytes: Vec<u8> = self.machine_sid.iter().flat_map(|v| v.to_le_bytes()).collect();
+                domain.set_value("MachineSid".to_string(), RegistryValue::new_binary("MachineSid".to_string(), bytes));
+            }
+        }
+        if let Some(next) = domain.get_value("NextRid").and_then(RegistryValue::as_dword) {
+            self.next_rid = next.max(FIRST_USER_RID);
+        }
+
+        self.accounts.clear();
+        if let Some(users) = registry.get_key_by_path(SAM_USERS_PATH) {
+            for rid_name in users.enumerate_subkeys() {
+                let Some(key) = registry.get_key_by_path(&format!("{}\\{}", SAM_USERS_PATH, rid_name)) else {
+                    continue;
+                };
+                if let Some(account) = account_from_key(&rid_name, key) {
+                    self.accounts.insert(account.name.to_lowercase(), account);
+                }
+            }
+        }
+        drop(registry);
+
+        if !self.accounts.values().any(|a| a.rid == DOMAIN_USER_RID_ADMIN) {
+            self.store(Account {
+                name: "Administrator".to_string(),
+                rid: DOMAIN_USER_RID_ADMIN,
+                flags: ACCOUNT_ADMIN,
+                salt: Vec::new(),
+                password_hash: Vec::new(),
+                iterations: 0,
+            });
+        }
+        if !self.accounts.values().any(|a| a.rid == DOMAIN_USER_RID_GUEST) {
+            self.store(Account {
+                name: "Guest".to_string(),
+                rid: DOMAIN_USER_RID_GUEST,
+                flags: ACCOUNT_DISABLED,
+                salt: Vec::new(),
+                password_hash: Vec::new(),
+                iterations: 0,
+            });
+        }
+    }
+
+    /// Write one account back to the SAM hive
+    fn store(&mut self, account: Account) {
+        {
+            let mut registry = REGISTRY_MANAGER.lock();
+            if let Some(key) = registry.create_key_by_path(&format!("{}\\{:08X}", SAM_USERS_PATH, account.rid)) {
+                key.set_value("Name".to_string(), RegistryValue::new_string("Name".to_string(), account.name.clone()));
+                key.set_value("Flags".to_string(), RegistryValue::new_dword("Flags".to_string(), account.flags));
+                key.set_value("Salt".to_string(), RegistryValue::new_binary("Salt".to_string(), account.salt.clone()));
+                key.set_value("Hash".to_string(), RegistryValue::new_binary("Hash".to_string(), account.password_hash.clone()));
+                key.set_value("Iterations".to_string(), RegistryValue::new_dword("Iterations".to_string(), account.iterations));
+            }
+            if let Some(domain) = registry.create_key_by_path(SAM_ACCOUNT_PATH) {
+                domain.set_value("NextRid".to_string(), RegistryValue::new_dword("NextRid".to_string(), self.next_rid));
+            }
+        }
+        self.accounts.insert(account.name.to_lowercase(), account);
+    }
+
+    pub fn account(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(&name.to_lowercase())
+    }
+
+    pub fn account_by_rid(&self, rid: u32) -> Option<&Account> {
+        self.accounts.values().find(|a| a.rid == rid)
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    pub fn user_sid(&self, rid: u32) -> Sid {
+        let [a, b, c] = self.machine_sid;
+        Sid::new(1, [0, 0, 0, 0, 0, 5], vec![21, a, b, c, rid])
+    }
+
+    /// RID of a SID issued by this machine
+    pub fn rid_of(&self, sid: &Sid) -> Option<u32> {
+        let [a, b, c] = self.machine_sid;
+        match sid.sub_authorities.as_slice() {
+            [21, x, y, z, rid] if [*x, *y, *z] == [a, b, c] => Some(*rid),
+            _ => None,
+        }
+    }
+
+    pub fn create_account(&mut self, name: &str, password: &str, admin: bool) -> Result<u32, NtStatus> {
+        if name.is_empty() || name.len() > 20 || name.contains(['\\', '/', ':', ' ']) {
+            return Err(NtStatus::InvalidParameter);
+        }
+        if self.account(name).is_some() {
+            return Err(NtStatus::ObjectNameCollision);
+        }
+
+        let rid = self.next_rid;
+        self.next_rid += 1;
+        let mut account = Account {
+            name: name.to_string(),
+            rid,
+            flags: if admin { ACCOUNT_ADMIN } else { 0 },
+            salt: Vec::new(),
+            password_hash: Vec::new(),
+            iterations: 0,
+        };
+        account.set_password(password)?;
+        self.store(account);
+        Ok(rid)
+    }
+
+    pub fn delete_account(&mut self, name: &str) -> Result<(), NtStatus> {
+        let account = self.account(name).ok_or(NtStatus::NoSuchUser)?;
+        if account.rid < FIRST_USER_RID {
+            // Built-in accounts can only be disabled
+            return Err(NtStatus::AccessDenied);
+        }
+        let rid = account.rid;
+        self.accounts.remove(&name.to_lowercase());
+
+        REGISTRY_MANAGER.lock().delete_key_by_path(&format!("{}\\{:08X}", SAM_USERS_PATH, rid));
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), NtStatus> {
+        let mut account = self.account(name).cloned().ok_or(NtStatus::NoSuchUser)?;
+        account.set_password(password)?;
+        self.store(account);
+        Ok(())
+    }
+
+    pub fn set_disabled(&mut self, name: &str, disabled: bool) -> Result<(), NtStatus> {
+        let mut account = self.account(name).cloned().ok_or(NtStatus::NoSuchUser)?;
+        if disabled {
+            account.flags |= ACCOUNT_DISABLED;
+        } else {
+            account.flags &= !ACCOUNT_DISABLED;
+        }
+        self.store(account);
+        Ok(())
+    }
+}
+
+fn account_from_key(rid_name: &str, key: &RegistryKey) -> Option<Account> {
+    let rid = u32::from_str_radix(rid_name, 16).ok()?;
+    let name = key.get_value("Name")?.as_string()?;
+    let dword = |value: &str| key.get_value(value).and_then(RegistryValue::as_dword).unwrap_or(0);
+    let binary = |value: &str| {
+        key.get_value(value)
+            .and_then(RegistryValue::as_binary)
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default()
+    };
+
+    Some(Account {
+        name,
+        rid,
+        flags: dword("Flags"),
+        salt: binary("Salt"),
+        password_hash: binary("Hash"),
+        iterations: dword("Iterations"),
+    })
+}
+
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, key_len: usize) -> CryptoResult<Vec<u8>> {
+    let hmac = Hmac::new(SHA256::new());
+    let mut derived = Vec::with_capacity(key_len);
+    let mut block = 1u32;
+
+    while derived.len() < key_len {
+        let mut input = salt.to_vec();
+        input.extend_from_slice(&block.to_be_bytes());
+        let mut u = hmac.compute(password, &input)?;
+        if u.is_empty() {
+            // No output would never fill the key
+            return Err(CryptoError::InvalidState);
+        }
+        let mut f = u.clone();
+        for _ in 1..iterations {
+            u = hmac.compute(password, &u)?;
+            for (f_byte, u_byte) in f.iter_mut().zip(u.iter()) {
+                *f_byte ^= u_byte;
+            }
+        }
+        derived.extend_from_slice(&f);
+        block += 1;
+    }
+
+    derived.truncate(key_len);
+    Ok(derived)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn privilege(privilege: Privilege, enabled: bool) -> LuidAndAttributes {
+    let attributes = if enabled {
+        PrivilegeAttributes::SE_PRIVILEGE_ENABLED | PrivilegeAttributes::SE_PRIVILEGE_ENABLED_BY_DEFAULT
+    } else {
+        PrivilegeAttributes::empty()
+    };
+    LuidAndAttributes {
+        luid: Luid::new(privilege as u64),
+        attributes,
+    }
+}
+
+const DEFAULT_GROUP: u32 = SE_GROUP_MANDATORY | SE_GROUP_ENABLED_BY_DEFAULT | SE_GROUP_ENABLED;
+
+fn build_token(database: &AccountDatabase, account: &Account) -> Result<Handle, NtStatus> {
+    let user_sid = database.user_sid(account.rid);
+    let mut groups = vec![
+        (WellKnownSids::world_sid(), DEFAULT_GROUP),
+        (WellKnownSids::users_sid(), DEFAULT_GROUP),
+        // NT AUTHORITY\INTERACTIVE
+        (Sid::new(1, [0, 0, 0, 0, 0, 5], vec![4]), DEFAULT_GROUP),
+    ];
+    let mut privileges = vec![
+        privilege(Privilege::ChangeNotify, true),
+        privilege(Privilege::Shutdown, false),
+        privilege(Privilege::Undock, false),
+        privilege(Privilege::TimeZone, false),
+    ];
+
+    if account.rid == DOMAIN_USER_RID_GUEST {
+        groups[1] = (WellKnownSids::guests_sid(), DEFAULT_GROUP);
+    }
+    if account.is_admin() {
+        groups.push((WellKnownSids::administrators_sid(), DEFAULT_GROUP));
+        privileges.extend([
+            privilege(Privilege::Security, false),
+            privilege(Privilege::TakeOwnership, false),
+            privilege(Privilege::LoadDriver, false),
+            privilege(Privilege::Systemtime, false),
+            privilege(Privilege::Backup, false),
+            privilege(Privilege::Restore, false),
+            privilege(Privilege::Debug, false),
+            privilege(Privilege::IncreaseBasePriority, false),
+            privilege(Privilege::CreateSymbolicLink, false),
+        ]);
+    }
+
+    SECURITY_MANAGER
+        .lock()
+        .create_token(TokenType::Primary, user_sid, groups, privileges)
+}
+
+fn log_logon(event: SecurityEvent, severity: Severity, user: &str, message: &str) {
+    let mut details = EventDetails::new();
+    details.additional_info.push(("user".to_string(), user.to_string()));
+    audit::log_event(event, severity, message, details);
+}
+
+/// Initialize the account database from the SAM hive
+pub fn init_accounts() {
+    ACCOUNTS.lock().load();
+}
+
+/// True until the Administrator account has been given a password
+pub fn needs_setup() -> bool {
+    ACCOUNTS
+        .lock()
+        .account_by_rid(DOMAIN_USER_RID_ADMIN)
+        .map_or(true, |admin| !admin.has_password())
+}
+
+/// Set the Administrator password during first-boot setup
+pub fn setup_administrator(password: &str) -> Result<(), NtStatus> {
+    if !needs_setup() {
+        return Err(NtStatus::AccessDenied);
+    }
+    if password.is_empty() {
+        return Err(NtStatus::InvalidParameter);
+    }
+    ACCOUNTS.lock().set_password("Administrator", password)
+}
+
+/// Verify credentials and create a primary token for the user
+pub fn logon_user(name: &str, password: &str) -> Result<Handle, NtStatus> {
+    let database = ACCOUNTS.lock();
+    let account = match database.account(name) {
+        Some(account) if account.verify_password(password) => account,
+        _ => {
+            drop(database);
+            log_logon(SecurityEvent::LoginFailure, Severity::Warning, name, &format!("Logon failure for {}", name));
+            return Err(NtStatus::LogonFailure);
+        }
+    };
+    if account.is_disabled() {
+        let name = account.name.clone();
+        drop(database);
+        log_logon(SecurityEvent::LoginFailure, Severity::Warning, &name, &format!("Logon to disabled account {}", name));
+        return Err(NtStatus::AccountDisabled);
+    }
+
+    let token = build_token(&database, account)?;
+    let name = account.name.clone();
+    drop(database);
+    log_logon(SecurityEvent::LoginSuccess, Severity::Info, &name, &format!("User {} logged on", name));
+    Ok(token)
+}
+
+/// Release a logon token
+pub fn logoff(token: Handle) {
+    let name = token_user_name(token).unwrap_or_default();
+    let _ = SECURITY_MANAGER.lock().close_token(token);
+    log_logon(SecurityEvent::LogoutSuccess, Severity::Info, &name, &format!("User {} logged off", name));
+}
+
+/// Account name for a token, or the well-known name for SYSTEM
+pub fn token_user_name(token: Handle) -> Option<String> {
+    let user_sid = SECURITY_MANAGER.lock().get_token(token)?.user_sid.clone();
+    if user_sid == WellKnownSids::system_sid() {
+        return Some("SYSTEM".to_string());
+    }
+    let database = ACCOUNTS.lock();
+    let rid = database.rid_of(&user_sid)?;
+    database.account_by_rid(rid).map(|account| account.name.clone())
+}
+
+/// SID of a local account
+pub fn account_sid(name: &str) -> Option<Sid> {
+    let database = ACCOUNTS.lock();
+    let rid = database.account(name)?.rid;
+    Some(database.user_sid(rid))
+}
+
+/// Whether the calling process runs as SYSTEM or an administrator
+pub fn caller_is_admin() -> bool {
+    token_is_admin(crate::process::current_token())
+}
+
+/// Whether a token is SYSTEM's or an administrator's
+pub fn token_is_admin(token: Handle) -> bool {
+    SECURITY_MANAGER
+        .lock()
+        .get_token(token)
+        .map_or(false, |token| token.is_admin())
+}
+
+/// Create an account; requires an administrator caller
+pub fn create_account(name: &str, password: &str, admin: bool) -> Result<u32, NtStatus> {
+    if !caller_is_admin() {
+        return Err(NtStatus::AccessDenied);
+    }
+    let rid = ACCOUNTS.lock().create_account(name, password, admin)?;
+    audit::log_security_event(SecurityEvent::ConfigurationChange, &format!("Account {} created", name));
+    Ok(rid)
+}
+
+/// Delete an account; requires an administrator caller
+pub fn delete_account(name: &str) -> Result<(), NtStatus> {
+    if !caller_is_admin() {
+        return Err(NtStatus::AccessDenied);
+    }
+    ACCOUNTS.lock().delete_account(name)?;
+    audit::log_security_event(SecurityEvent::ConfigurationChange, &format!("Account {} deleted", name));
+    Ok(())
+}
+
+/// Change a password. Users may change their own after proving the old one;
+/// administrators may reset any account.
+pub fn change_password(name: &str, old_password: Option<&str>, new_password: &str) -> Result<(), NtStatus> {
+    let caller = token_user_name(crate::process::current_token()).unwrap_or_default();
+    let is_self = caller.eq_ignore_ascii_case(name);
+
+    if !caller_is_admin() {
+        let verified = is_self
+            && old_password.map_or(false, |old| {
+                ACCOUNTS.lock().account(name).map_or(false, |a| a.verify_password(old))
+            });
+        if !verified {
+            return Err(NtStatus::AccessDenied);
+        }
+    }
+    ACCOUNTS.lock().set_password(name, new_password)
+}
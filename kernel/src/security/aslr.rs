@@ -0,0 +1,100 @@
+// User-mode address space layout randomization
+//
+// Every address space gets its own stack top, heap start and mmap search base,
+// and relocatable PE/ELF images are loaded at a random base instead of their
+// preferred one. Offsets are drawn from the per-boot entropy pool that also
+// seeds KASLR (RDRAND mixed with TSC jitter), which places kernel drivers
+// at random in its module area the same way.
+use crate::memory::userspace::{USER_HEAP_START, USER_SPACE_START, USER_STACK_TOP};
+use crate::serial_println;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static ASLR_ENABLED: AtomicBool = AtomicBool::new(false);
+
+const PAGE_SIZE: u64 = 0x1000;
+// Allocation granularity; image bases are aligned to it as on Windows
+const IMAGE_ALIGNMENT: u64 = 0x10000;
+
+const STACK_RANDOM_PAGES: u64 = 1 << 22; // 16GB below the top of user space
+const HEAP_RANDOM_PAGES: u64 = 1 << 18; // 1GB above the default heap start
+const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+const MMAP_RANDOM_PAGES: u64 = 1 << 28; // 1TB
+// Everything between the randomized heap and the mmap base, 16TB of 64KB
+// slots
+const IMAGE_REGION_BASE: u64 = 0x0000_0001_0000_0000;
+const IMAGE_REGION_SIZE: u64 = MMAP_BASE - IMAGE_REGION_BASE;
+
+// Randomized bases of one address space
+#[derive(Debug, Clone, Copy)]
+pub struct UserLayout {
+    pub stack_top: u64,
+    pub heap_start: u64,
+    pub mmap_base: u64,
+}
+
+impl UserLayout {
+    // The deterministic layout used when ASLR is off
+    pub const fn fixed() -> Self {
+        Self {
+            stack_top: USER_STACK_TOP,
+            heap_start: USER_HEAP_START,
+            mmap_base: USER_SPACE_START,
+        }
+    }
+}
+
+pub fn init() -> bool {
+    if ASLR_ENABLED.load(Ordering::SeqCst) {
+        return true;
+    }
+
+    // Seed the pool now so the first process does not pay for it
+    let _ = super::kaslr::random_u64();
+    ASLR_ENABLED.store(true, Ordering::SeqCst);
+
+    let sample = randomize_layout();
+    serial_println!(
+        "[ASLR] Sample layout: stack 0x{:x}, heap 0x{:x}, mmap 0x{:x}",
+        sample.stack_top,
+        sample.heap_start,
+        sample.mmap_base
+    );
+    true
+}
+
+pub fn is_enabled() -> bool {
+    ASLR_ENABLED.load(Ordering::SeqCst)
+}
+
+// Uniform offset of up to `range` units of `unit` bytes
+fn random_offset(range: u64, unit: u64) -> u64 {
+    (super::kaslr::random_u64() % range) * unit
+}
+
+/// Fresh layout for a new address space
+pub fn randomize_layout() -> UserLayout {
+    if !is_enabled() {
+        return UserLayout::fixed();
+    }
+
+    UserLayout {
+        stack_top: USER_STACK_TOP - random_offset(STACK_RANDOM_PAGES, PAGE_SIZE),
+        heap_start: USER_HEAP_START + random_offset(HEAP_RANDOM_PAGES, PAGE_SIZE),
+        mmap_base: MMAP_BASE + random_offset(MMAP_RANDOM_PAGES, PAGE_SIZE),
+    }
+}
+
+/// Load address for an image of `image_size` bytes. Images without
+/// relocation information keep their preferred base.
+pub fn randomize_image_base(preferred: u64, image_size: u64, relocatable: bool) -> u64 {
+    if !is_enabled() || !relocatable {
+        return preferred;
+    }
+
+    let image_slots = (image_size + IMAGE_ALIGNMENT - 1) / IMAGE_ALIGNMENT;
+    let slots = (IMAGE_REGION_SIZE / IMAGE_ALIGNMENT).saturating_sub(image_slots);
+    if slots == 0 {
+        return preferred;
+    }
+    IMAGE_REGION_BASE + random_offset(slots, IMAGE_ALIGNMENT)
+}
@@ -83,9 +83,10 @@ pub enum SecurityEvent {
     KernelIntegrityCheck,
     ModuleIntegrityFailure,
     ConfigurationChange,
+    ProtectedMemoryWrite,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Severity {
     Debug,
     Info,
@@ -94,6 +95,19 @@ pub enum Severity {
     Critical,
 }
 
+impl Severity {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "debug" => Some(Severity::Debug),
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "error" => Some(Severity::Error),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EventDetails {
     pub source_ip: Option<String>,
@@ -214,6 +228,7 @@ pub fn log_event(
     
     // Store event
     store_event(event.clone());
+    super::audit_log::append(&event);
     
     // Output to serial for critical events
     if matches!(event.severity, Severity::Critical | Severity::Error) {
@@ -397,6 +412,8 @@ pub fn export_audit_log() -> String {
     output
 }
 
+/// Clear the in-memory log; the persistent log is append-only and keeps
+/// its records
 pub fn clear_audit_log() {
     if !AUDIT_ENABLED.load(Ordering::SeqCst) {
         return;
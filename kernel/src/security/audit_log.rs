@@ -0,0 +1,491 @@
+// Persistent, tamper-evident security audit log
+//
+// Events at or above the persistence threshold are appended to numbered
+// segment files under LOG_DIR, one tab-separated line per record. Each record
+// ends with SHA-256(previous record hash || record body) and each segment
+// opens with a header naming the hash it continues from, so editing,
+// removing or reordering records breaks the chain. Rotation deletes the
+// oldest segments; verification then anchors at the oldest surviving header.
+//
+// log_event is reached with the VFS lock held (file access auditing), so
+// records are chained and queued under LOG_STATE and only written when the
+// VFS can be taken with try_lock. Lock order is VFS -> LOG_STATE.
+use super::audit::{AuditEvent, Severity};
+use crate::crypto;
+use crate::fs::vfs::{VirtualFileSystem, VFS};
+use crate::serial_println;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const LOG_DIR: &str = "/windows/system32/winevt/logs";
+const SEGMENT_PREFIX: &str = "security-";
+const SEGMENT_SUFFIX: &str = ".log";
+const HEADER_MAGIC: &str = "#AUDIT1";
+
+const MAX_SEGMENT_SIZE: usize = 64 * 1024;
+const MAX_SEGMENTS: u32 = 16;
+const MAX_PENDING: usize = 4096;
+
+struct LogState {
+    enabled: bool,
+    min_severity: Severity,
+    // Hash of the newest chained record, written or not
+    chain_hash: [u8; 32],
+    // Hash of the newest record on disk; the next segment header carries it
+    written_hash: [u8; 32],
+    segment: u32,
+    contents: Vec<u8>,
+    pending: VecDeque<(String, [u8; 32])>,
+    dropped: u64,
+}
+
+static LOG_STATE: Mutex<LogState> = Mutex::new(LogState {
+    enabled: false,
+    min_severity: Severity::Info,
+    chain_hash: [0; 32],
+    written_hash: [0; 32],
+    segment: 0,
+    contents: Vec::new(),
+    pending: VecDeque::new(),
+    dropped: 0,
+});
+
+// One record read back from disk
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub id: u64,
+    pub timestamp: u64,
+    pub severity: Severity,
+    pub event: String,
+    pub pid: u64,
+    pub uid: u32,
+    pub message: String,
+    pub details: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub min_severity: Option<Severity>,
+    pub event: Option<String>,
+    pub pid: Option<u64>,
+    pub uid: Option<u32>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub text: Option<String>,
+    // Keep only the newest `limit` matches
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    // A segment file could not be read
+    Unreadable { segment: u32 },
+    // Missing or malformed segment header
+    BadHeader { segment: u32 },
+    // Header does not continue from the previous segment
+    SegmentGap { segment: u32 },
+    // A record line could not be parsed
+    Malformed { segment: u32, line: usize },
+    // A record's hash does not match its contents and predecessor
+    HashMismatch { segment: u32, line: usize, id: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub first_segment: u32,
+    pub last_segment: u32,
+    pub segments: usize,
+    pub records: usize,
+    pub anchor: [u8; 32],
+    pub error: Option<ChainError>,
+}
+
+/// Open the on-disk log once the filesystem is mounted and persist the
+/// events recorded in memory so far
+pub fn init() {
+    let backlog = super::audit::get_audit_log(None);
+    {
+        let mut vfs = VFS.lock();
+        let mut state = LOG_STATE.lock();
+        if state.enabled {
+            return;
+        }
+
+        create_log_dir(&mut vfs);
+        let segments = list_segments(&vfs);
+        match segments.last() {
+            Some(&newest) => {
+                let contents = vfs.read_file_unchecked(&segment_path(newest)).unwrap_or_default();
+                let tail = tail_hash(&contents);
+                state.segment = newest;
+                state.chain_hash = tail;
+                state.written_hash = tail;
+                state.contents = contents;
+            }
+            None => {
+                state.segment = 1;
+                state.contents = segment_header(1, &[0; 32]).into_bytes();
+                if vfs.write_file_unchecked(&segment_path(1), &state.contents).is_err() {
+                    serial_println!("[AUDIT] Cannot create {}, persistence disabled", LOG_DIR);
+                    return;
+                }
+            }
+        }
+        state.enabled = true;
+        serial_println!("[AUDIT] Persistent log at {} (segment {})", LOG_DIR, state.segment);
+    }
+
+    for event in backlog.iter() {
+        append(event);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    LOG_STATE.lock().enabled
+}
+
+/// Lowest severity written to disk
+pub fn set_min_severity(severity: Severity) {
+    LOG_STATE.lock().min_severity = severity;
+}
+
+pub fn min_severity() -> Severity {
+    LOG_STATE.lock().min_severity
+}
+
+/// Chain an event into the log and write it out if the VFS is free
+pub fn append(event: &AuditEvent) {
+    {
+        let mut state = LOG_STATE.lock();
+        if !state.enabled || event.severity < state.min_severity {
+            return;
+        }
+        // Dropped before chaining, so the chain itself stays intact
+        if state.pending.len() >= MAX_PENDING {
+            state.dropped += 1;
+            return;
+        }
+
+        let body = record_body(event);
+        let hash = chain(&state.chain_hash, &body);
+        state.chain_hash = hash;
+        state.pending.push_back((body, hash));
+    }
+
+    if let Some(mut vfs) = VFS.try_lock() {
+        write_pending(&mut vfs, &mut LOG_STATE.lock());
+    }
+}
+
+/// Write all queued records, waiting for the VFS
+pub fn flush() {
+    let mut vfs = VFS.lock();
+    write_pending(&mut vfs, &mut LOG_STATE.lock());
+}
+
+fn write_pending(vfs: &mut VirtualFileSystem, state: &mut LogState) {
+    if !state.enabled || state.pending.is_empty() {
+        return;
+    }
+    if state.dropped > 0 {
+        serial_println!("[AUDIT] {} events dropped while the log was unavailable", state.dropped);
+        state.dropped = 0;
+    }
+
+    while let Some((body, hash)) = state.pending.pop_front() {
+        let line = format!("{}\t{}\n", body, hex(&hash));
+        if state.contents.len() + line.len() > MAX_SEGMENT_SIZE {
+            rotate(vfs, state);
+        }
+        state.contents.extend_from_slice(line.as_bytes());
+        state.written_hash = hash;
+    }
+
+    if let Err(error) = vfs.write_file_unchecked(&segment_path(state.segment), &state.contents) {
+        serial_println!("[AUDIT] Failed to write audit segment {}: {:?}", state.segment, error);
+    }
+}
+
+// Close the current segment and start the next one, pruning old segments
+fn rotate(vfs: &mut VirtualFileSystem, state: &mut LogState) {
+    let _ = vfs.write_file_unchecked(&segment_path(state.segment), &state.contents);
+
+    state.segment += 1;
+    state.contents = segment_header(state.segment, &state.written_hash).into_bytes();
+
+    for old in list_segments(vfs) {
+        if old + MAX_SEGMENTS <= state.segment {
+            let _ = vfs.delete_unchecked(&segment_path(old));
+        }
+    }
+}
+
+/// Walk every surviving segment and recompute the hash chain
+pub fn verify() -> VerifyReport {
+    let mut vfs = VFS.lock();
+    write_pending(&mut vfs, &mut LOG_STATE.lock());
+
+    let segments = list_segments(&vfs);
+    let mut report = VerifyReport {
+        first_segment: segments.first().copied().unwrap_or(0),
+        last_segment: segments.last().copied().unwrap_or(0),
+        segments: segments.len(),
+        records: 0,
+        anchor: [0; 32],
+        error: None,
+    };
+
+    let mut running: Option<[u8; 32]> = None;
+    for &segment in &segments {
+        let Ok(data) = vfs.read_file_unchecked(&segment_path(segment)) else {
+            report.error = Some(ChainError::Unreadable { segment });
+            return report;
+        };
+        let text = String::from_utf8_lossy(&data);
+        let mut lines = text.lines();
+
+        let Some(prev) = lines.next().and_then(|header| parse_header(header, segment)) else {
+            report.error = Some(ChainError::BadHeader { segment });
+            return report;
+        };
+        let mut hash = match running {
+            None => {
+                report.anchor = prev;
+                prev
+            }
+            Some(hash) if hash == prev => hash,
+            Some(_) => {
+                report.error = Some(ChainError::SegmentGap { segment });
+                return report;
+            }
+        };
+
+        for (index, line) in lines.enumerate() {
+            let line_no = index + 2;
+            let Some((body, stored)) = split_record(line) else {
+                report.error = Some(ChainError::Malformed { segment, line: line_no });
+                return report;
+            };
+            hash = chain(&hash, body);
+            if hex(&hash) != stored {
+                let id = parse_record(line).map_or(0, |record| record.id);
+                report.error = Some(ChainError::HashMismatch { segment, line: line_no, id });
+                return report;
+            }
+            report.records += 1;
+        }
+        running = Some(hash);
+    }
+
+    report
+}
+
+/// Records on disk matching `query`, oldest first
+pub fn query(query: &AuditQuery) -> Vec<AuditRecord> {
+    let mut vfs = VFS.lock();
+    write_pending(&mut vfs, &mut LOG_STATE.lock());
+
+    let mut matches = Vec::new();
+    for segment in list_segments(&vfs) {
+        let Ok(data) = vfs.read_file_unchecked(&segment_path(segment)) else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(&data);
+        for line in text.lines().skip(1) {
+            if let Some(record) = parse_record(line) {
+                if matches_query(&record, query) {
+                    matches.push(record);
+                }
+            }
+        }
+    }
+
+    if let Some(limit) = query.limit {
+        let excess = matches.len().saturating_sub(limit);
+        matches.drain(..excess);
+    }
+    matches
+}
+
+fn matches_query(record: &AuditRecord, query: &AuditQuery) -> bool {
+    query.min_severity.map_or(true, |min| record.severity >= min)
+        && query.event.as_ref().map_or(true, |event| record.event.eq_ignore_ascii_case(event))
+        && query.pid.map_or(true, |pid| record.pid == pid)
+        && query.uid.map_or(true, |uid| record.uid == uid)
+        && query.since.map_or(true, |since| record.timestamp >= since)
+        && query.until.map_or(true, |until| record.timestamp <= until)
+        && query.text.as_ref().map_or(true, |text| {
+            record.message.contains(text.as_str()) || record.details.contains(text.as_str())
+        })
+}
+
+fn chain(prev: &[u8; 32], body: &str) -> [u8; 32] {
+    let mut input = Vec::with_capacity(32 + body.len());
+    input.extend_from_slice(prev);
+    input.extend_from_slice(body.as_bytes());
+    crypto::sha256(&input)
+}
+
+// Everything but the trailing hash field
+fn record_body(event: &AuditEvent) -> String {
+    let details = &event.details;
+    let mut fields: Vec<String> = Vec::new();
+    if let Some(ip) = &details.source_ip {
+        fields.push(format!("source_ip={}", ip));
+    }
+    if let Some(path) = &details.target_path {
+        fields.push(format!("path={}", path));
+    }
+    if let Some(syscall) = details.syscall {
+        fields.push(format!("syscall={}", syscall));
+    }
+    if let Some(code) = details.error_code {
+        fields.push(format!("error={:#x}", code));
+    }
+    for (key, value) in &details.additional_info {
+        fields.push(format!("{}={}", key, value));
+    }
+
+    format!(
+        "{}\t{}\t{:?}\t{:?}\t{}\t{}\t{}\t{}",
+        event.id,
+        event.timestamp,
+        event.severity,
+        event.event_type,
+        event.pid,
+        event.uid,
+        escape(&event.message),
+        escape(&fields.join("; "))
+    )
+}
+
+fn split_record(line: &str) -> Option<(&str, &str)> {
+    let (body, hash) = line.rsplit_once('\t')?;
+    (hash.len() == 64).then_some((body, hash))
+}
+
+fn parse_record(line: &str) -> Option<AuditRecord> {
+    let (body, _) = split_record(line)?;
+    let fields: Vec<&str> = body.split('\t').collect();
+    if fields.len() != 8 {
+        return None;
+    }
+    Some(AuditRecord {
+        id: fields[0].parse().ok()?,
+        timestamp: fields[1].parse().ok()?,
+        severity: Severity::from_name(fields[2])?,
+        event: fields[3].to_string(),
+        pid: fields[4].parse().ok()?,
+        uid: fields[5].parse().ok()?,
+        message: unescape(fields[6]),
+        details: unescape(fields[7]),
+    })
+}
+
+fn segment_header(segment: u32, prev: &[u8; 32]) -> String {
+    format!("{}\t{}\t{}\n", HEADER_MAGIC, segment, hex(prev))
+}
+
+fn parse_header(line: &str, segment: u32) -> Option<[u8; 32]> {
+    let mut fields = line.split('\t');
+    if fields.next()? != HEADER_MAGIC || fields.next()?.parse::<u32>().ok()? != segment {
+        return None;
+    }
+    unhex(fields.next()?)
+}
+
+// Hash to continue from: the last record's, or the header's if empty
+fn tail_hash(contents: &[u8]) -> [u8; 32] {
+    let text = String::from_utf8_lossy(contents);
+    let mut lines = text.lines();
+    let header = lines.next().and_then(|line| {
+        let mut fields = line.split('\t');
+        fields.nth(2).and_then(unhex)
+    });
+    lines
+        .filter_map(split_record)
+        .last()
+        .and_then(|(_, hash)| unhex(hash))
+        .or(header)
+        .unwrap_or([0; 32])
+}
+
+fn segment_path(segment: u32) -> String {
+    format!("{}/{}{:06}{}", LOG_DIR, SEGMENT_PREFIX, segment, SEGMENT_SUFFIX)
+}
+
+// Segment numbers present on disk, ascending
+fn list_segments(vfs: &VirtualFileSystem) -> Vec<u32> {
+    let mut segments: Vec<u32> = vfs
+        .list_directory_unchecked(LOG_DIR)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|info| {
+            let name = info.name.to_lowercase();
+            name.strip_prefix(SEGMENT_PREFIX)?.strip_suffix(SEGMENT_SUFFIX)?.parse().ok()
+        })
+        .collect();
+    segments.sort_unstable();
+    segments
+}
+
+fn create_log_dir(vfs: &mut VirtualFileSystem) {
+    let mut path = String::new();
+    for component in LOG_DIR.split('/').filter(|c| !c.is_empty()) {
+        path.push('/');
+        path.push_str(component);
+        // Existing directories report an error, which is fine here
+        let _ = vfs.create_directory_unchecked(&path);
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
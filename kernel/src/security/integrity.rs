@@ -5,7 +5,6 @@ use alloc::collections::BTreeMap;
 use alloc::{vec, format};
 use spin::Mutex;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-// use sha2::{Sha256, Digest}; // Temporarily disabled due to build issues
 
 static INTEGRITY_ENABLED: AtomicBool = AtomicBool::new(false);
 static INTEGRITY_CHECKS_RUN: AtomicU64 = AtomicU64::new(0);
@@ -92,24 +91,8 @@ fn get_kernel_sections() -> Vec<KernelSection> {
 }
 
 fn calculate_hash(data: *const u8, size: usize) -> [u8; 32] {
-    // Temporarily return a dummy hash due to sha2 build issues
-    // let mut hasher = Sha256::new();
-    // 
-    // let slice = unsafe { core::slice::from_raw_parts(data, size) };
-    // hasher.update(slice);
-    // 
-    // let result = hasher.finalize();
-    // let mut hash = [0u8; 32];
-    // hash.copy_from_slice(&result);
-    // hash
-    
-    // Simple checksum as temporary replacement
     let slice = unsafe { core::slice::from_raw_parts(data, size) };
-    let mut hash = [0u8; 32];
-    for (i, &byte) in slice.iter().enumerate() {
-        hash[i % 32] ^= byte;
-    }
-    hash
+    crate::crypto::sha256(slice)
 }
 
 fn setup_periodic_checks() {
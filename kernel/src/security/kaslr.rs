@@ -13,6 +13,16 @@ const MAX_KERNEL_SLIDE: u64 = 0x1000_0000;  // 256MB maximum slide
 const MODULE_SLIDE_RANGE: u64 = 0x800_0000; // 128MB range for modules
 const ENTROPY_POOL_SIZE: usize = 64;
 
+// Driver images are mapped in a region of their own, each at a random
+// 64KB-aligned address in it
+const MODULE_AREA_BASE: u64 = 0xFFFF_FC00_0000_0000;
+const MODULE_AREA_SIZE: u64 = 0x100_0000_0000; // 1TB
+const MODULE_ALIGNMENT: u64 = 0x10000;
+const MODULE_PLACEMENT_TRIES: usize = 16;
+
+// Module area ranges in use, base to size
+static MODULE_RANGES: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+
 pub struct KaslrConfig {
     pub kernel_base: VirtAddr,
     pub kernel_size: u64,
@@ -41,11 +51,8 @@ impl EntropySource {
         let mut entropy = 0u64;
         
         // Use RDTSC for timing entropy
-        unsafe {
-            let tsc: u64;
-            core::arch::asm!("rdtsc", out("rax") tsc, out("rdx") _);
-            entropy ^= tsc;
-        }
+        entropy ^= read_tsc();
+        entropy ^= tsc_jitter();
         
         // Use RDRAND if available
         if crate::cpu::get_info().has_rdrand() {
@@ -86,8 +93,37 @@ impl EntropySource {
     }
 }
 
+fn read_tsc() -> u64 {
+    unsafe {
+        let tsc: u64;
+        core::arch::asm!("rdtsc", "shl rdx, 32", "or rax, rdx", out("rax") tsc, out("rdx") _);
+        tsc
+    }
+}
+
+// The low bits of TSC deltas around a short memory-touching loop vary with
+// cache, pipeline and interrupt state; fold sixteen of them together
+fn tsc_jitter() -> u64 {
+    let mut scratch = [0u8; 64];
+    let mut jitter = 0u64;
+    for round in 0..16u8 {
+        let start = read_tsc();
+        for (i, byte) in scratch.iter_mut().enumerate() {
+            *byte = byte.wrapping_add(round ^ i as u8);
+        }
+        core::hint::black_box(&scratch);
+        jitter = jitter.rotate_left(4) ^ read_tsc().wrapping_sub(start);
+    }
+    jitter
+}
+
 static ENTROPY: Mutex<Option<EntropySource>> = Mutex::new(None);
 
+/// Next value from the per-boot entropy pool, seeding it on first use
+pub fn random_u64() -> u64 {
+    ENTROPY.lock().get_or_insert_with(EntropySource::new).get_random()
+}
+
 pub fn init() -> bool {
     if KASLR_ENABLED.load(Ordering::SeqCst) {
         return true;
@@ -150,6 +186,45 @@ pub fn randomize_address(base: VirtAddr, is_module: bool) -> VirtAddr {
     }
 }
 
+/// Reserve `size` bytes of the module area for a driver image. The range
+/// is at a random address when KASLR is on and the lowest free one when it
+/// is off; either way a 64KB gap separates it from its neighbours.
+pub fn reserve_module_range(size: u64) -> Option<VirtAddr> {
+    let span = size.checked_add(MODULE_ALIGNMENT - 1)? / MODULE_ALIGNMENT * MODULE_ALIGNMENT;
+    let slots = (MODULE_AREA_SIZE / MODULE_ALIGNMENT).checked_sub(span / MODULE_ALIGNMENT)?;
+    if span == 0 || slots == 0 {
+        return None;
+    }
+
+    let mut ranges = MODULE_RANGES.lock();
+    let free = |ranges: &BTreeMap<u64, u64>, base: u64| {
+        ranges.iter().all(|(&other, &other_size)| {
+            base + span + MODULE_ALIGNMENT <= other || other + other_size + MODULE_ALIGNMENT <= base
+        })
+    };
+
+    let random = KASLR_ENABLED.load(Ordering::SeqCst).then(|| {
+        (0..MODULE_PLACEMENT_TRIES)
+            .map(|_| MODULE_AREA_BASE + (random_u64() % slots) * MODULE_ALIGNMENT)
+            .find(|&base| free(&ranges, base))
+    });
+    let base = match random.flatten() {
+        Some(base) => base,
+        // Disabled, or the area is crowded: first fit after each range
+        None => core::iter::once(MODULE_AREA_BASE)
+            .chain(ranges.iter().map(|(&other, &other_size)| other + other_size + MODULE_ALIGNMENT))
+            .filter(|&base| base + span <= MODULE_AREA_BASE + MODULE_AREA_SIZE)
+            .find(|&base| free(&ranges, base))?,
+    };
+    ranges.insert(base, span);
+    Some(VirtAddr::new(base))
+}
+
+/// Return a range taken with `reserve_module_range`
+pub fn release_module_range(base: VirtAddr) {
+    MODULE_RANGES.lock().remove(&base.as_u64());
+}
+
 pub fn get_kernel_slide() -> u64 {
     KERNEL_SLIDE.load(Ordering::SeqCst)
 }
@@ -1,9 +1,15 @@
+use crate::boot::cmdline::Param;
 use crate::serial_println;
-use x86_64::{VirtAddr, registers::control::{Cr3, Cr3Flags}};
-use core::sync::atomic::{AtomicBool, Ordering, fence};
+use x86_64::{VirtAddr, registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags}};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering, fence};
 use core::arch::asm;
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
 use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::audit::{self, EventDetails, SecurityEvent, Severity};
 
 static SPECTRE_MITIGATION: AtomicBool = AtomicBool::new(false);
 static MELTDOWN_MITIGATION: AtomicBool = AtomicBool::new(false);
@@ -350,7 +356,7 @@ pub fn enable_rop_protection() -> bool {
     
     // Enable Intel CET if available
     if enable_cet() {
-        serial_println!("[MITIGATIONS] Intel CET enabled");
+        serial_println!("[MITIGATIONS] Intel CET enabled for user processes");
     }
     
     // Set up control flow integrity
@@ -363,44 +369,303 @@ pub fn enable_rop_protection() -> bool {
     true
 }
 
+// Control-flow enforcement for user processes
+//
+// CET keeps a second copy of each return address on a shadow stack, which
+// ordinary stores cannot reach, and a `ret` whose two copies differ raises
+// a control protection fault; indirect branch tracking faults an indirect
+// call or jump that lands anywhere but an `endbr64`. CR4.CET turns both on
+// for the CPU and IA32_U_CET for user mode, which the scheduler loads per
+// process along with its shadow stack pointer. The loader gives each
+// process a shadow stack below its user stack. ELF images get the features
+// their GNU property note says they were built for, and images without one
+// shadow stacks only; a process may give up either, or lock what it has,
+// with the cet_control system call.
+
+const IA32_U_CET: u32 = 0x6A0;
+const IA32_PL3_SSP: u32 = 0x6A7;
+const U_CET_SH_STK_EN: u64 = 1 << 0;
+const U_CET_ENDBR_EN: u64 = 1 << 2;
+// Branches with a notrack prefix, as in jump tables, need no endbr64
+const U_CET_NO_TRACK_EN: u64 = 1 << 4;
+
+// GNU_PROPERTY_X86_FEATURE_1_AND bits
+const GNU_FEATURE_IBT: u32 = 1 << 0;
+const GNU_FEATURE_SHSTK: u32 = 1 << 1;
+
+// An exception frame's token: the shadow stack pointer to go back to, with
+// bit 63 set so it passes for neither a return address nor a restore token
+const FRAME_TOKEN: u64 = 1 << 63;
+
+/// cet_control operations
+pub const CET_GET: usize = 0;
+pub const CET_DISABLE: usize = 1;
+pub const CET_LOCK: usize = 2;
+
+static USER_CET: Param<bool> =
+    Param::new("security.cet", true, "CET shadow stacks and branch tracking for user processes");
+
+// What the CPU has and the command line allows
+static CET_SUPPORTED: AtomicU32 = AtomicU32::new(0);
+
+bitflags::bitflags! {
+    /// The control-flow enforcement a user process runs with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CetFeatures: u32 {
+        const SHADOW_STACK = 1 << 0;
+        const IBT = 1 << 1;
+    }
+}
+
+/// A process's CET state, kept in its PCB while it is switched out
+#[derive(Debug, Clone)]
+pub struct CetState {
+    pub features: CetFeatures,
+    /// No more changes through cet_control
+    pub locked: bool,
+    pub shadow_stack: Option<core::ops::Range<VirtAddr>>,
+    /// IA32_PL3_SSP
+    pub ssp: u64,
+    /// IA32_U_CET, which also holds where branch tracking had got to
+    pub u_cet: u64,
+}
+
+impl CetState {
+    pub fn new() -> Self {
+        CetState { features: CetFeatures::empty(), locked: false, shadow_stack: None, ssp: 0, u_cet: 0 }
+    }
+
+    /// The state for a new image, whose shadow stack would be at
+    /// `shadow_stack`; `x86_features` is the ELF image's GNU property, if
+    /// it has one
+    pub fn for_image(x86_features: Option<u32>, shadow_stack: core::ops::Range<VirtAddr>) -> Self {
+        let wanted = match x86_features {
+            Some(bits) => {
+                let mut features = CetFeatures::empty();
+                features.set(CetFeatures::SHADOW_STACK, bits & GNU_FEATURE_SHSTK != 0);
+                features.set(CetFeatures::IBT, bits & GNU_FEATURE_IBT != 0);
+                features
+            }
+            None => CetFeatures::SHADOW_STACK,
+        };
+        let mut state = CetState { features: wanted & cet_supported(), ..CetState::new() };
+        if state.features.contains(CetFeatures::SHADOW_STACK) {
+            state.ssp = shadow_stack.end.as_u64();
+            state.shadow_stack = Some(shadow_stack);
+        }
+        state.u_cet = state.u_cet_bits();
+        state
+    }
+
+    /// The state for a new thread of a process in this one: the same
+    /// features, and its own shadow stack at `shadow_stack` if they include
+    /// one
+    pub fn for_thread(&self, shadow_stack: Option<core::ops::Range<VirtAddr>>) -> Self {
+        let mut state = CetState { features: self.features, locked: self.locked, ..CetState::new() };
+        if let Some(shadow_stack) = shadow_stack.filter(|_| self.features.contains(CetFeatures::SHADOW_STACK)) {
+            state.ssp = shadow_stack.end.as_u64();
+            state.shadow_stack = Some(shadow_stack);
+        }
+        state.u_cet = state.u_cet_bits();
+        state
+    }
+
+    fn u_cet_bits(&self) -> u64 {
+        let mut bits = 0;
+        if self.features.contains(CetFeatures::SHADOW_STACK) {
+            bits |= U_CET_SH_STK_EN;
+        }
+        if self.features.contains(CetFeatures::IBT) {
+            bits |= U_CET_ENDBR_EN | U_CET_NO_TRACK_EN;
+        }
+        bits
+    }
+}
+
+/// The CET features user processes may have
+pub fn cet_supported() -> CetFeatures {
+    CetFeatures::from_bits_truncate(CET_SUPPORTED.load(Ordering::Relaxed))
+}
+
+// CR4.CET cannot be set without CR0.WP, which strict memory protection
+// sets before this runs
 fn enable_cet() -> bool {
-    // Check for CET support
-    if !check_cet_support() {
+    let supported = check_cet_support();
+    if supported.is_empty() || !USER_CET.get() || !Cr0::read().contains(Cr0Flags::WRITE_PROTECT) {
         return false;
     }
-    
-    // CET is already partially enabled in stack_protection.rs
-    // Here we enable additional CET features
     unsafe {
-        // Enable indirect branch tracking
-        let cet_msr = 0x6A2; // IA32_PL3_SSP
-        let mut value = read_msr(cet_msr);
-        value |= 1; // Enable IBT
-        write_msr(cet_msr, value);
+        Cr4::write(Cr4::read() | Cr4Flags::CONTROL_FLOW_ENFORCEMENT);
+        // Nothing is enforced until a process is loaded with its own
+        write_msr(IA32_U_CET, 0);
     }
-    
+    CET_SUPPORTED.store(supported.bits(), Ordering::Relaxed);
+    serial_println!("[MITIGATIONS] CET for user processes: {:?}", supported);
     true
 }
 
-fn check_cet_support() -> bool {
+// Shadow stacks are CPUID.(7,0):ECX bit 7, IBT EDX bit 20
+fn check_cet_support() -> CetFeatures {
+    let ecx: u32;
+    let edx: u32;
     unsafe {
-        let result: u32;
-        let ecx_out: u32;
         asm!(
             "push rbx",
             "cpuid",
             "pop rbx",
             inout("eax") 7u32 => _,
-            inout("ecx") 0u32 => ecx_out,
-            out("edx") _,
+            inout("ecx") 0u32 => ecx,
+            out("edx") edx,
             options(preserves_flags),
         );
-        result = ecx_out;
-        
-        // Check CET bits
-        (result & (1 << 7)) != 0 // CET_SS
-            && (result & (1 << 20)) != 0 // CET_IBT
     }
+    let mut features = CetFeatures::empty();
+    features.set(CetFeatures::SHADOW_STACK, ecx & (1 << 7) != 0);
+    features.set(CetFeatures::IBT, edx & (1 << 20) != 0);
+    features
+}
+
+/// Load a process's CET state as it is switched in
+pub fn load_user_cet(state: &CetState) {
+    if cet_supported().is_empty() {
+        return;
+    }
+    unsafe {
+        write_msr(IA32_U_CET, state.u_cet);
+        write_msr(IA32_PL3_SSP, state.ssp);
+    }
+}
+
+/// Save the running process's CET state as it is switched out
+pub fn save_user_cet(state: &mut CetState) {
+    if cet_supported().is_empty() || state.features.is_empty() {
+        return;
+    }
+    unsafe {
+        state.u_cet = read_msr(IA32_U_CET);
+        state.ssp = read_msr(IA32_PL3_SSP);
+    }
+}
+
+/// Push a token onto the running process's shadow stack before one of its
+/// exception handlers runs, so that the frame's return can be checked
+pub fn push_frame_token() -> Result<(), &'static str> {
+    unsafe {
+        if cet_supported().is_empty() || read_msr(IA32_U_CET) & U_CET_SH_STK_EN == 0 {
+            return Ok(());
+        }
+        let ssp = read_msr(IA32_PL3_SSP);
+        let token = ssp.checked_sub(8).ok_or("Shadow stack exhausted")?;
+        // A user mode write to the shadow stack, allowed the kernel only
+        asm!("wrussq [{}], {}", in(reg) token, in(reg) ssp | FRAME_TOKEN, options(nostack));
+        write_msr(IA32_PL3_SSP, token);
+    }
+    Ok(())
+}
+
+/// Pop the token an exception frame pushed as the frame's context is
+/// restored. A shadow stack with no token on top was tampered with.
+pub fn pop_frame_token() -> Result<(), &'static str> {
+    unsafe {
+        if cet_supported().is_empty() || read_msr(IA32_U_CET) & U_CET_SH_STK_EN == 0 {
+            return Ok(());
+        }
+        let ssp = read_msr(IA32_PL3_SSP);
+        let token = core::ptr::read_volatile(ssp as *const u64);
+        if token != (ssp + 8) | FRAME_TOKEN {
+            return Err("No exception frame token on the shadow stack");
+        }
+        write_msr(IA32_PL3_SSP, ssp + 8);
+    }
+    Ok(())
+}
+
+/// cet_control for the running process: get its features, give some up,
+/// or lock them
+pub fn cet_control(state: &mut CetState, op: usize, features: usize) -> Result<CetFeatures, &'static str> {
+    match op {
+        CET_GET => return Ok(state.features),
+        _ if state.locked => return Err("CET settings are locked"),
+        CET_DISABLE => {
+            let features = u32::try_from(features).map_err(|_| "Unknown CET features")?;
+            let features = CetFeatures::from_bits(features).ok_or("Unknown CET features")?;
+            save_user_cet(state);
+            state.features -= features;
+            // The tracker state stays; only the enable bits change
+            state.u_cet = (state.u_cet & !(U_CET_SH_STK_EN | U_CET_ENDBR_EN | U_CET_NO_TRACK_EN)) | state.u_cet_bits();
+            load_user_cet(state);
+        }
+        CET_LOCK => state.locked = true,
+        _ => return Err("Unknown CET operation"),
+    }
+    Ok(state.features)
+}
+
+// What a control protection fault's error code says broke
+fn cp_reason(error_code: u64) -> &'static str {
+    match error_code & 0x7FFF {
+        1 => "return address mismatch",
+        2 => "far return address mismatch",
+        3 => "indirect branch without endbr64",
+        4 => "bad shadow stack restore token",
+        5 => "busy supervisor shadow stack",
+        _ => "unknown",
+    }
+}
+
+// The NT status of a process ended for breaking control flow, as on Windows
+const STATUS_STACK_BUFFER_OVERRUN: u32 = 0xC000_0409;
+
+// The last control protection fault, for `poll`, as the audit log cannot
+// be taken from an exception
+static CP_UNREPORTED: AtomicU64 = AtomicU64::new(0);
+static CP_PID: AtomicU64 = AtomicU64::new(0);
+static CP_RIP: AtomicU64 = AtomicU64::new(0);
+static CP_ERROR: AtomicU64 = AtomicU64::new(0);
+
+/// The control protection fault: a process that broke its control flow is
+/// ended. The kernel runs without CET, so one there is a bug.
+pub fn handle_control_protection(frame: &InterruptStackFrame, error_code: u64, frame_pointer: u64) {
+    let rip = frame.instruction_pointer.as_u64();
+    if frame.code_segment & 3 != 3 {
+        crate::debug::oops("control protection fault", frame, frame_pointer);
+        panic!("Control protection fault in the kernel: {} at {:#x}", cp_reason(error_code), rip);
+    }
+    // A fault inside the executor cannot wait for it
+    let Some(mut executor) = crate::process::executor::EXECUTOR.try_lock() else {
+        serial_println!("[CET] Control protection fault at {:#x}, and the process table is busy", rip);
+        return;
+    };
+    let Some(pid) = executor.get_current_pid() else {
+        return;
+    };
+    serial_println!("[CET] Process {} ended: {} at {:#x}", pid, cp_reason(error_code), rip);
+    CP_PID.store(pid as u64, Ordering::Relaxed);
+    CP_RIP.store(rip, Ordering::Relaxed);
+    CP_ERROR.store(error_code, Ordering::Relaxed);
+    CP_UNREPORTED.fetch_add(1, Ordering::Release);
+    executor.terminate_process(pid, STATUS_STACK_BUFFER_OVERRUN as i32);
+}
+
+/// Log the security events for control protection faults since the last
+/// call; from the main loop
+pub fn poll() {
+    let faults = CP_UNREPORTED.swap(0, Ordering::Acquire);
+    if faults == 0 {
+        return;
+    }
+    let error_code = CP_ERROR.load(Ordering::Relaxed);
+    let mut details = EventDetails::new();
+    details.error_code = Some(error_code as u32);
+    details.additional_info.push((String::from("pid"), format!("{}", CP_PID.load(Ordering::Relaxed))));
+    details.additional_info.push((String::from("rip"), format!("{:#x}", CP_RIP.load(Ordering::Relaxed))));
+    details.additional_info.push((String::from("faults"), format!("{}", faults)));
+    audit::log_event(
+        SecurityEvent::RopAttackBlocked,
+        Severity::Critical,
+        &format!("Control-flow violation: {}", cp_reason(error_code)),
+        details,
+    );
 }
 
 fn setup_cfi() {
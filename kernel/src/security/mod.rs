@@ -1,4 +1,5 @@
 pub mod kaslr;
+pub mod aslr;
 pub mod stack_protection;
 pub mod memory_protection;
 pub mod secure_boot;
@@ -6,9 +7,12 @@ pub mod capabilities;
 pub mod sandbox;
 pub mod mitigations;
 pub mod audit;
+pub mod audit_log;
 pub mod integrity;
+pub mod watchpoints;
 pub mod keyring;
 pub mod tpm;
+pub mod accounts;
 
 use crate::serial_println;
 
@@ -16,6 +20,8 @@ use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
 use alloc::vec::Vec;
 use alloc::string::String;
+use alloc::format;
+use crate::boot::cmdline::{Param, Value};
 
 static SECURITY_INITIALIZED: AtomicBool = AtomicBool::new(false);
 static SECURITY_FEATURES: AtomicU64 = AtomicU64::new(0);
@@ -42,11 +48,13 @@ pub enum SecurityFeature {
     IntegerOverflowDetection = 1 << 16,
     AuditingEnabled = 1 << 17,
     IntegrityChecking = 1 << 18,
+    UserAslr = 1 << 19,
 }
 
 #[derive(Clone, Copy)]
 pub struct SecurityConfig {
     pub kaslr_enabled: bool,
+    pub aslr_enabled: bool,
     pub stack_protection_level: StackProtectionLevel,
     pub memory_protection_strict: bool,
     pub secure_boot_required: bool,
@@ -69,10 +77,71 @@ pub enum AuditLevel {
     Verbose,    // All security events
 }
 
+impl Value for StackProtectionLevel {
+    const KIND: &'static str = "none|basic|enhanced|maximum";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "none" => Some(StackProtectionLevel::None),
+            "basic" => Some(StackProtectionLevel::Basic),
+            "enhanced" => Some(StackProtectionLevel::Enhanced),
+            "maximum" => Some(StackProtectionLevel::Maximum),
+            _ => None,
+        }
+    }
+
+    fn show(&self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
+impl Value for AuditLevel {
+    const KIND: &'static str = "none|critical|normal|verbose";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "none" => Some(AuditLevel::None),
+            "critical" => Some(AuditLevel::Critical),
+            "normal" => Some(AuditLevel::Normal),
+            "verbose" => Some(AuditLevel::Verbose),
+            _ => None,
+        }
+    }
+
+    fn show(&self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
+static KASLR: Param<bool> = Param::new("security.kaslr", true, "Randomize the kernel's address");
+static ASLR: Param<bool> = Param::new("security.aslr", true, "Randomize user address space layouts");
+static STACK_PROTECTION: Param<StackProtectionLevel> =
+    Param::new("security.stack", StackProtectionLevel::Enhanced, "Stack protection level");
+static STRICT_MEMORY: Param<bool> =
+    Param::new("security.strict_memory", true, "W^X, SMEP, SMAP and heap hardening");
+static REQUIRE_SECURE_BOOT: Param<bool> =
+    Param::new("security.require_secure_boot", false, "Require a kernel verified by Secure Boot");
+static AUDIT: Param<AuditLevel> = Param::new("security.audit", AuditLevel::Normal, "Security audit level");
+
+impl SecurityConfig {
+    /// The defaults, as overridden by `security.*` options
+    pub fn from_cmdline() -> Self {
+        Self {
+            kaslr_enabled: KASLR.get(),
+            aslr_enabled: ASLR.get(),
+            stack_protection_level: STACK_PROTECTION.get(),
+            memory_protection_strict: STRICT_MEMORY.get(),
+            secure_boot_required: REQUIRE_SECURE_BOOT.get(),
+            audit_level: AUDIT.get(),
+        }
+    }
+}
+
 impl Default for SecurityConfig {
     fn default() -> Self {
         Self {
             kaslr_enabled: true,
+            aslr_enabled: true,
             stack_protection_level: StackProtectionLevel::Enhanced,
             memory_protection_strict: true,
             secure_boot_required: false,
@@ -103,6 +172,14 @@ pub fn init(config: SecurityConfig) {
         }
     }
     
+    // Randomize user address space layouts
+    if config.aslr_enabled {
+        if aslr::init() {
+            features |= SecurityFeature::UserAslr as u64;
+            serial_println!("[SECURITY] User ASLR enabled");
+        }
+    }
+    
     // Initialize stack protection
     match config.stack_protection_level {
         StackProtectionLevel::None => {},
@@ -183,6 +260,10 @@ pub fn init(config: SecurityConfig) {
     keyring::init_keyring();
     serial_println!("[SECURITY] Keyring subsystem initialized");
     
+    // Load local accounts from the SAM hive
+    accounts::init_accounts();
+    serial_println!("[SECURITY] Account database loaded");
+    
     // Initialize TPM if available
     if let Ok(()) = tpm::init_tpm() {
         serial_println!("[SECURITY] TPM 2.0 device initialized");
@@ -191,11 +272,20 @@ pub fn init(config: SecurityConfig) {
     }
     
     SECURITY_FEATURES.store(features, Ordering::SeqCst);
+
+    // Watch critical structures for writes, the feature mask among them
+    watchpoints::init();
+
     SECURITY_INITIALIZED.store(true, Ordering::SeqCst);
     
     serial_println!("[SECURITY] Security subsystem initialized with features: 0x{:x}", features);
 }
 
+/// Where the enabled feature mask is kept, for the watchpoints
+pub(crate) fn features_address() -> u64 {
+    &SECURITY_FEATURES as *const AtomicU64 as u64
+}
+
 pub fn is_feature_enabled(feature: SecurityFeature) -> bool {
     let features = SECURITY_FEATURES.load(Ordering::SeqCst);
     (features & (feature as u64)) != 0
@@ -4,9 +4,13 @@ use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::collections::BTreeSet;
 use alloc::{vec, format};
+use crate::fs::security::normalize_path;
+use crate::process::{ProcessId, PROCESS_MANAGER};
+use crate::syscall::SyscallNumber;
 use spin::Mutex;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use super::audit::{log_event, EventDetails, SecurityEvent, Severity};
 use super::capabilities::{CapabilitySet, Capability};
 
 static SANDBOXING_ENABLED: AtomicBool = AtomicBool::new(false);
@@ -20,8 +24,12 @@ pub struct Sandbox {
     pub capabilities: CapabilitySet,
     pub resource_limits: ResourceLimits,
     pub allowed_syscalls: BTreeSet<u32>,
+    pub syscall_rules: Vec<SyscallRule>,
     pub filesystem_view: FilesystemView,
     pub network_policy: NetworkPolicy,
+    pub on_violation: ViolationAction,
+    // Sandbox of the process that launched this one; its restrictions apply too
+    pub parent: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -185,6 +193,7 @@ fn create_driver_sandbox() {
             max_threads: 10,
         },
         allowed_syscalls: get_driver_syscalls(),
+        syscall_rules: vec![],
         filesystem_view: FilesystemView {
             root: String::from("/"),
             allowed_paths: vec![],
@@ -192,6 +201,8 @@ fn create_driver_sandbox() {
             read_only_paths: vec![],
         },
         network_policy: NetworkPolicy::none(),
+        on_violation: ViolationAction::Deny,
+        parent: None,
     };
     
     SANDBOXES.lock().insert(sandbox.id, sandbox);
@@ -205,8 +216,11 @@ fn create_user_sandbox() {
         capabilities: super::capabilities::create_sandbox_capabilities(),
         resource_limits: ResourceLimits::default(),
         allowed_syscalls: get_user_syscalls(),
+        syscall_rules: vec![],
         filesystem_view: FilesystemView::isolated(String::from("/home/sandbox")),
         network_policy: NetworkPolicy::outbound_only(),
+        on_violation: ViolationAction::Deny,
+        parent: None,
     };
     
     SANDBOXES.lock().insert(sandbox.id, sandbox);
@@ -217,33 +231,37 @@ fn allocate_sandbox_id() -> u64 {
 }
 
 fn get_driver_syscalls() -> BTreeSet<u32> {
-    let mut syscalls = BTreeSet::new();
     // Allow only essential driver syscalls
-    syscalls.insert(0x00); // read
-    syscalls.insert(0x01); // write
-    syscalls.insert(0x09); // mmap
-    syscalls.insert(0x0B); // munmap
-    syscalls.insert(0x10); // ioctl
-    syscalls
+    [
+        SyscallNumber::Exit,
+        SyscallNumber::Read,
+        SyscallNumber::Write,
+        SyscallNumber::Mmap,
+        SyscallNumber::Munmap,
+    ]
+    .iter()
+    .map(|&syscall| syscall as u32)
+    .collect()
 }
 
 fn get_user_syscalls() -> BTreeSet<u32> {
-    let mut syscalls = BTreeSet::new();
     // Allow common user syscalls
-    syscalls.insert(0x00); // read
-    syscalls.insert(0x01); // write
-    syscalls.insert(0x02); // open
-    syscalls.insert(0x03); // close
-    syscalls.insert(0x04); // stat
-    syscalls.insert(0x05); // fstat
-    syscalls.insert(0x09); // mmap
-    syscalls.insert(0x0B); // munmap
-    syscalls.insert(0x0C); // brk
-    syscalls.insert(0x27); // getpid
-    syscalls.insert(0x66); // getuid
-    syscalls.insert(0x68); // getgid
-    syscalls.insert(0xE7); // exit_group
-    syscalls
+    [
+        SyscallNumber::Exit,
+        SyscallNumber::Read,
+        SyscallNumber::Write,
+        SyscallNumber::Open,
+        SyscallNumber::Close,
+        SyscallNumber::GetPid,
+        SyscallNumber::Brk,
+        SyscallNumber::Mmap,
+        SyscallNumber::Munmap,
+        SyscallNumber::Sleep,
+        SyscallNumber::GetTime,
+    ]
+    .iter()
+    .map(|&syscall| syscall as u32)
+    .collect()
 }
 
 pub fn create_sandbox(name: String, policy: SandboxPolicy) -> u64 {
@@ -264,12 +282,15 @@ pub fn create_sandbox(name: String, policy: SandboxPolicy) -> u64 {
         } else {
             get_user_syscalls()
         },
+        syscall_rules: vec![],
         filesystem_view: FilesystemView::isolated(format!("/sandbox/{}", sandbox_id)),
         network_policy: if policy.allow_network {
             NetworkPolicy::outbound_only()
         } else {
             NetworkPolicy::none()
         },
+        on_violation: ViolationAction::Deny,
+        parent: None,
     };
     
     SANDBOXES.lock().insert(sandbox_id, sandbox);
@@ -307,6 +328,7 @@ pub fn get_sandbox(sandbox_id: u64) -> Option<Sandbox> {
 #[derive(Debug)]
 pub enum SandboxError {
     InvalidSandbox,
+    InvalidProfile(usize), // line of the offending directive
     PolicyViolation,
     ResourceExceeded,
     SyscallDenied,
@@ -314,134 +336,471 @@ pub enum SandboxError {
     NetworkDenied,
 }
 
-pub fn check_syscall(pid: u64, syscall_num: u32) -> Result<(), SandboxError> {
-    if !SANDBOXING_ENABLED.load(Ordering::SeqCst) {
-        return Ok(());
-    }
-    
-    if let Some(sandbox_id) = get_process_sandbox(pid) {
-        if let Some(sandbox) = get_sandbox(sandbox_id) {
-            if !sandbox.allowed_syscalls.contains(&syscall_num) {
-                serial_println!("[SANDBOX] Process {} denied syscall 0x{:x}", pid, syscall_num);
-                return Err(SandboxError::SyscallDenied);
-            }
+// What happens to a process that breaks its sandbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationAction {
+    Deny, // fail the offending operation
+    Kill, // terminate the process
+}
+
+// Constraint on the value of one syscall argument
+#[derive(Debug, Clone)]
+pub enum ArgConstraint {
+    Equals(u64),
+    OneOf(Vec<u64>),
+    Range(u64, u64), // inclusive
+    Mask(u64),       // no bits outside the mask may be set
+}
+
+impl ArgConstraint {
+    pub fn allows(&self, value: u64) -> bool {
+        match self {
+            ArgConstraint::Equals(expected) => value == *expected,
+            ArgConstraint::OneOf(values) => values.contains(&value),
+            ArgConstraint::Range(low, high) => (*low..=*high).contains(&value),
+            ArgConstraint::Mask(mask) => value & !mask == 0,
         }
     }
-    
-    Ok(())
 }
 
-pub fn check_path_access(pid: u64, path: &str, write: bool) -> Result<(), SandboxError> {
-    if !SANDBOXING_ENABLED.load(Ordering::SeqCst) {
-        return Ok(());
+// A permitted syscall, provided every argument constraint holds
+#[derive(Debug, Clone)]
+pub struct SyscallRule {
+    pub number: u32,
+    pub args: Vec<(usize, ArgConstraint)>, // argument index from 0
+}
+
+impl SyscallRule {
+    pub fn allow(number: u32) -> Self {
+        Self { number, args: Vec::new() }
     }
-    
-    if let Some(sandbox_id) = get_process_sandbox(pid) {
-        if let Some(sandbox) = get_sandbox(sandbox_id) {
-            // Check if path is denied
-            for denied in &sandbox.filesystem_view.denied_paths {
-                if path.starts_with(denied) {
-                    return Err(SandboxError::PathDenied);
-                }
+
+    pub fn with_arg(mut self, index: usize, constraint: ArgConstraint) -> Self {
+        self.args.push((index, constraint));
+        self
+    }
+
+    pub fn matches(&self, number: u32, args: &[u64; 6]) -> bool {
+        self.number == number
+            && self.args.iter().all(|(index, constraint)| {
+                args.get(*index).map_or(false, |&value| constraint.allows(value))
+            })
+    }
+}
+
+// Declarative description of the sandbox a child process is launched in
+#[derive(Debug, Clone)]
+pub struct SandboxProfile {
+    pub name: String,
+    pub syscalls: Vec<SyscallRule>,
+    pub filesystem: FilesystemView,
+    pub network: NetworkPolicy,
+    pub resource_limits: ResourceLimits,
+    pub on_violation: ViolationAction,
+}
+
+impl SandboxProfile {
+    /// An empty profile: no syscalls, no files and no network
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            syscalls: Vec::new(),
+            filesystem: FilesystemView {
+                root: String::new(),
+                allowed_paths: vec![],
+                denied_paths: vec![],
+                read_only_paths: vec![],
+            },
+            network: NetworkPolicy::none(),
+            resource_limits: ResourceLimits::default(),
+            on_violation: ViolationAction::Deny,
+        }
+    }
+
+    /// Parse the text form of a profile, one directive per line and `#`
+    /// starting a comment:
+    ///
+    /// ```text
+    /// name      <name>
+    /// syscall   <name|number> [arg<N>=<v> | arg<N>=<v>,<v>,.. | arg<N>=<lo>..<hi> | arg<N>&<mask>]...
+    /// root      <path>
+    /// allow     <path>
+    /// deny      <path>
+    /// readonly  <path>
+    /// network   on|off|outbound
+    /// limit     memory|cpu|filesize|files|processes|threads <n>
+    /// violation deny|kill
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, SandboxError> {
+        let mut profile = Self::new("profile");
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
             }
-            
-            // Check if path is read-only
-            if write {
-                for readonly in &sandbox.filesystem_view.read_only_paths {
-                    if path.starts_with(readonly) {
-                        return Err(SandboxError::PathDenied);
+            let invalid = || SandboxError::InvalidProfile(index + 1);
+            let mut words = line.split_whitespace();
+            let directive = words.next().unwrap_or("");
+            let args: Vec<&str> = words.collect();
+
+            match (directive, args.as_slice()) {
+                ("name", [name]) => profile.name = name.to_string(),
+                ("syscall", [syscall, constraints @ ..]) => {
+                    let mut rule = SyscallRule::allow(parse_syscall(syscall).ok_or_else(invalid)?);
+                    for constraint in constraints {
+                        let (arg, constraint) = parse_constraint(constraint).ok_or_else(invalid)?;
+                        rule = rule.with_arg(arg, constraint);
                     }
+                    profile.syscalls.push(rule);
                 }
-            }
-            
-            // Check if path is outside sandbox root
-            if !path.starts_with(&sandbox.filesystem_view.root) {
-                let mut allowed = false;
-                for allowed_path in &sandbox.filesystem_view.allowed_paths {
-                    if path.starts_with(allowed_path) {
-                        allowed = true;
-                        break;
-                    }
+                ("root", [path]) => profile.filesystem.root = normalize_path(path),
+                ("allow", [path]) => profile.filesystem.allowed_paths.push(normalize_path(path)),
+                ("deny", [path]) => profile.filesystem.denied_paths.push(normalize_path(path)),
+                ("readonly", [path]) => profile.filesystem.read_only_paths.push(normalize_path(path)),
+                ("network", ["on"]) => profile.network = NetworkPolicy::unrestricted(),
+                ("network", ["off"]) => profile.network = NetworkPolicy::none(),
+                ("network", ["outbound"]) => {
+                    profile.network = NetworkPolicy { allow_outbound: true, ..NetworkPolicy::none() }
                 }
-                if !allowed {
-                    return Err(SandboxError::PathDenied);
+                ("limit", [resource, value]) => {
+                    let value = parse_number(value).ok_or_else(invalid)?;
+                    let limits = &mut profile.resource_limits;
+                    match *resource {
+                        "memory" => limits.max_memory = value,
+                        "cpu" => limits.max_cpu_time = value,
+                        "filesize" => limits.max_file_size = value,
+                        "files" => limits.max_open_files = value as u32,
+                        "processes" => limits.max_processes = value as u32,
+                        "threads" => limits.max_threads = value as u32,
+                        _ => return Err(invalid()),
+                    }
                 }
+                ("violation", ["deny"]) => profile.on_violation = ViolationAction::Deny,
+                ("violation", ["kill"]) => profile.on_violation = ViolationAction::Kill,
+                _ => return Err(invalid()),
             }
         }
+
+        Ok(profile)
     }
-    
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_syscall(text: &str) -> Option<u32> {
+    parse_number(text)
+        .map(|number| number as u32)
+        .or_else(|| SyscallNumber::from_name(text).map(|syscall| syscall as u32))
+}
+
+// `arg<N>` counts from 1, like the registers the dispatcher names arg1..arg6
+fn parse_constraint(text: &str) -> Option<(usize, ArgConstraint)> {
+    let rest = text.strip_prefix("arg")?;
+    let split = rest.find(|c| c == '=' || c == '&')?;
+    let arg = rest[..split].parse::<usize>().ok().filter(|arg| (1..=6).contains(arg))?;
+    let value = &rest[split + 1..];
+
+    let constraint = if rest.as_bytes()[split] == b'&' {
+        ArgConstraint::Mask(parse_number(value)?)
+    } else if let Some((low, high)) = value.split_once("..") {
+        ArgConstraint::Range(parse_number(low)?, parse_number(high)?)
+    } else if value.contains(',') {
+        ArgConstraint::OneOf(value.split(',').map(parse_number).collect::<Option<Vec<_>>>()?)
+    } else {
+        ArgConstraint::Equals(parse_number(value)?)
+    };
+    Some((arg - 1, constraint))
+}
+
+/// Register a sandbox built from a profile, nested inside `parent` when the
+/// launching process is itself sandboxed
+pub fn create_sandbox_from_profile(profile: &SandboxProfile, parent: Option<u64>) -> u64 {
+    let sandbox_id = allocate_sandbox_id();
+
+    // Unconstrained rules go in the plain syscall set
+    let (unconstrained, syscall_rules): (Vec<SyscallRule>, Vec<SyscallRule>) =
+        profile.syscalls.iter().cloned().partition(|rule| rule.args.is_empty());
+    let allowed_syscalls: BTreeSet<u32> = unconstrained.iter().map(|rule| rule.number).collect();
+    let exec = SyscallNumber::Exec as u32;
+
+    let sandbox = Sandbox {
+        id: sandbox_id,
+        name: profile.name.clone(),
+        policy: SandboxPolicy {
+            allow_network: profile.network.allow_outbound || profile.network.allow_inbound,
+            allow_filesystem: !profile.filesystem.root.is_empty()
+                || !profile.filesystem.allowed_paths.is_empty(),
+            allow_ipc: false,
+            allow_devices: false,
+            allow_exec: allowed_syscalls.contains(&exec)
+                || syscall_rules.iter().any(|rule| rule.number == exec),
+            strict_mode: false,
+        },
+        capabilities: match parent.and_then(get_sandbox) {
+            Some(parent) => parent.capabilities,
+            None => super::capabilities::create_sandbox_capabilities(),
+        },
+        resource_limits: profile.resource_limits.clone(),
+        allowed_syscalls,
+        syscall_rules,
+        filesystem_view: profile.filesystem.clone(),
+        network_policy: profile.network.clone(),
+        on_violation: profile.on_violation,
+        parent,
+    };
+
+    SANDBOXES.lock().insert(sandbox_id, sandbox);
+
+    sandbox_id
+}
+
+/// Launch a child of `parent` confined by `profile`. A sandboxed parent's
+/// restrictions keep applying to the child, so a profile can only narrow
+/// what the parent already has.
+pub fn spawn_sandboxed(parent: u64, name: String, profile: &SandboxProfile) -> Result<u64, SandboxError> {
+    let children = PROCESS_MANAGER
+        .lock()
+        .get_process(ProcessId(parent as u32))
+        .map(|process| process.children.len() as u64)
+        .ok_or(SandboxError::InvalidSandbox)?;
+    check_resource_limit(parent, ResourceType::Processes, children + 1)?;
+
+    let sandbox_id = create_sandbox_from_profile(profile, get_process_sandbox(parent));
+    let child = PROCESS_MANAGER.lock().create_process(name, Some(ProcessId(parent as u32)));
+    assign_process_to_sandbox(child.0 as u64, sandbox_id)?;
+
+    serial_println!(
+        "[SANDBOX] Process {} launched in sandbox '{}' by process {}",
+        child.0,
+        profile.name,
+        parent
+    );
+    Ok(child.0 as u64)
+}
+
+// Children launched without a profile stay in their parent's sandbox
+pub fn inherit_sandbox(parent: u64, child: u64) {
+    if let Some(sandbox_id) = get_process_sandbox(parent) {
+        let _ = assign_process_to_sandbox(child, sandbox_id);
+    }
+}
+
+// The process's sandbox followed by every sandbox enclosing it
+fn sandbox_chain(pid: u64) -> Vec<Sandbox> {
+    let mut chain = Vec::new();
+    if !SANDBOXING_ENABLED.load(Ordering::SeqCst) {
+        return chain;
+    }
+
+    let mut next = get_process_sandbox(pid);
+    let sandboxes = SANDBOXES.lock();
+    while let Some(sandbox) = next.and_then(|id| sandboxes.get(&id)) {
+        next = sandbox.parent;
+        chain.push(sandbox.clone());
+    }
+    chain
+}
+
+/// Whether a violation by this process terminates it
+pub fn violation_action(pid: u64) -> ViolationAction {
+    if sandbox_chain(pid).iter().any(|sandbox| sandbox.on_violation == ViolationAction::Kill) {
+        ViolationAction::Kill
+    } else {
+        ViolationAction::Deny
+    }
+}
+
+// Audit a violation and apply the sandbox's violation action
+fn report_violation(
+    pid: u64,
+    sandbox: &Sandbox,
+    error: SandboxError,
+    description: &str,
+    details: EventDetails,
+) -> SandboxError {
+    serial_println!("[SANDBOX] Process {} in sandbox '{}': {}", pid, sandbox.name, description);
+
+    let mut details = details;
+    details.additional_info.push(("pid".to_string(), pid.to_string()));
+    details.additional_info.push(("sandbox_id".to_string(), sandbox.id.to_string()));
+    log_event(
+        SecurityEvent::SandboxViolation,
+        Severity::Error,
+        &format!("Sandbox '{}' violation by process {}: {}", sandbox.name, pid, description),
+        details,
+    );
+
+    if violation_action(pid) == ViolationAction::Kill {
+        serial_println!("[SANDBOX] Killing process {}", pid);
+        PROCESS_MANAGER.lock().terminate_process(ProcessId(pid as u32));
+    }
+    error
+}
+
+impl Sandbox {
+    fn permits_syscall(&self, number: u32, args: &[u64; 6]) -> bool {
+        self.allowed_syscalls.contains(&number)
+            || self.syscall_rules.iter().any(|rule| rule.matches(number, args))
+    }
+}
+
+impl FilesystemView {
+    fn permits(&self, path: &str, write: bool) -> bool {
+        if self.denied_paths.iter().any(|denied| path_within(path, denied)) {
+            return false;
+        }
+        if write && self.read_only_paths.iter().any(|readonly| path_within(path, readonly)) {
+            return false;
+        }
+        path_within(path, &self.root) || self.allowed_paths.iter().any(|allowed| path_within(path, allowed))
+    }
+}
+
+impl NetworkPolicy {
+    pub fn unrestricted() -> Self {
+        Self {
+            allow_outbound: true,
+            allow_inbound: true,
+            ..Self::none()
+        }
+    }
+
+    fn permits(&self, address: &str, port: u16, outbound: bool) -> bool {
+        let direction = if outbound { self.allow_outbound } else { self.allow_inbound };
+        direction
+            && (self.allowed_ports.is_empty() || self.allowed_ports.contains(&port))
+            && !self.denied_addresses.iter().any(|denied| denied == address)
+            && (self.allowed_addresses.is_empty() || self.allowed_addresses.iter().any(|allowed| allowed == address))
+    }
+}
+
+impl ResourceLimits {
+    fn exceeded_by(&self, resource: ResourceType, amount: u64) -> bool {
+        match resource {
+            ResourceType::Memory => amount > self.max_memory,
+            ResourceType::CpuTime => amount > self.max_cpu_time,
+            ResourceType::FileSize => amount > self.max_file_size,
+            ResourceType::OpenFiles => amount > self.max_open_files as u64,
+            ResourceType::Processes => amount > self.max_processes as u64,
+            ResourceType::Threads => amount > self.max_threads as u64,
+        }
+    }
+}
+
+// Component-wise, case-insensitive prefix test on normalized paths. An empty
+// prefix contains nothing.
+fn path_within(path: &str, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return false;
+    }
+    let prefix = normalize_path(prefix).to_lowercase();
+    if prefix == "/" {
+        return true;
+    }
+    let path = path.to_lowercase();
+    path == prefix || path.strip_prefix(prefix.as_str()).map_or(false, |rest| rest.starts_with('/'))
+}
+
+/// Filter a syscall against the caller's sandbox, including argument
+/// constraints. Called by the dispatcher before the handler runs.
+pub fn check_syscall(pid: u64, syscall_num: u32, args: &[u64; 6]) -> Result<(), SandboxError> {
+    for sandbox in sandbox_chain(pid) {
+        if !sandbox.permits_syscall(syscall_num, args) {
+            let mut details = EventDetails::new();
+            details.syscall = Some(syscall_num);
+            return Err(report_violation(
+                pid,
+                &sandbox,
+                SandboxError::SyscallDenied,
+                &format!("syscall {} denied", syscall_num),
+                details,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_path_access(pid: u64, path: &str, write: bool) -> Result<(), SandboxError> {
+    let path = normalize_path(path);
+    for sandbox in sandbox_chain(pid) {
+        if !sandbox.filesystem_view.permits(&path, write) {
+            let mut details = EventDetails::new();
+            details.target_path = Some(path.clone());
+            let access = if write { "write" } else { "read" };
+            return Err(report_violation(
+                pid,
+                &sandbox,
+                SandboxError::PathDenied,
+                &format!("{} access to {} denied", access, path),
+                details,
+            ));
+        }
+    }
+
     Ok(())
 }
 
 pub fn check_network_access(pid: u64, address: &str, port: u16, outbound: bool) -> Result<(), SandboxError> {
-    if !SANDBOXING_ENABLED.load(Ordering::SeqCst) {
-        return Ok(());
+    for sandbox in sandbox_chain(pid) {
+        if !sandbox.network_policy.permits(address, port, outbound) {
+            let mut details = EventDetails::new();
+            details.source_ip = Some(address.to_string());
+            let direction = if outbound { "outbound" } else { "inbound" };
+            return Err(report_violation(
+                pid,
+                &sandbox,
+                SandboxError::NetworkDenied,
+                &format!("{} connection {}:{} denied", direction, address, port),
+                details,
+            ));
+        }
     }
-    
-    if let Some(sandbox_id) = get_process_sandbox(pid) {
-        if let Some(sandbox) = get_sandbox(sandbox_id) {
-            let policy = &sandbox.network_policy;
-            
-            // Check direction
-            if outbound && !policy.allow_outbound {
-                return Err(SandboxError::NetworkDenied);
-            }
-            if !outbound && !policy.allow_inbound {
-                return Err(SandboxError::NetworkDenied);
-            }
-            
-            // Check port
-            if !policy.allowed_ports.is_empty() && !policy.allowed_ports.contains(&port) {
-                return Err(SandboxError::NetworkDenied);
-            }
-            
-            // Check address
-            for denied in &policy.denied_addresses {
-                if address == denied {
-                    return Err(SandboxError::NetworkDenied);
-                }
-            }
-            
-            if !policy.allowed_addresses.is_empty() {
-                if !policy.allowed_addresses.contains(&address.to_string()) {
-                    return Err(SandboxError::NetworkDenied);
-                }
-            }
+
+    Ok(())
+}
+
+/// Whether the process may use the network at all
+pub fn check_network_enabled(pid: u64) -> Result<(), SandboxError> {
+    for sandbox in sandbox_chain(pid) {
+        let policy = &sandbox.network_policy;
+        if !policy.allow_outbound && !policy.allow_inbound {
+            return Err(report_violation(
+                pid,
+                &sandbox,
+                SandboxError::NetworkDenied,
+                "network access denied",
+                EventDetails::new(),
+            ));
         }
     }
-    
+
     Ok(())
 }
 
 pub fn check_resource_limit(pid: u64, resource: ResourceType, amount: u64) -> Result<(), SandboxError> {
-    if !SANDBOXING_ENABLED.load(Ordering::SeqCst) {
-        return Ok(());
-    }
-    
-    if let Some(sandbox_id) = get_process_sandbox(pid) {
-        if let Some(sandbox) = get_sandbox(sandbox_id) {
-            let limits = &sandbox.resource_limits;
-            
-            let exceeded = match resource {
-                ResourceType::Memory => amount > limits.max_memory,
-                ResourceType::CpuTime => amount > limits.max_cpu_time,
-                ResourceType::FileSize => amount > limits.max_file_size,
-                ResourceType::OpenFiles => amount > limits.max_open_files as u64,
-                ResourceType::Processes => amount > limits.max_processes as u64,
-                ResourceType::Threads => amount > limits.max_threads as u64,
-            };
-            
-            if exceeded {
-                serial_println!("[SANDBOX] Process {} exceeded {:?} limit", pid, resource);
-                return Err(SandboxError::ResourceExceeded);
-            }
+    for sandbox in sandbox_chain(pid) {
+        if sandbox.resource_limits.exceeded_by(resource, amount) {
+            return Err(report_violation(
+                pid,
+                &sandbox,
+                SandboxError::ResourceExceeded,
+                &format!("{:?} limit exceeded", resource),
+                EventDetails::new(),
+            ));
         }
     }
-    
+
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ResourceType {
     Memory,
     CpuTime,
@@ -460,6 +819,6 @@ pub fn enforce_sandbox_policy(pid: u64) -> Result<(), SandboxError> {
             }
         }
     }
-    
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -4,7 +4,7 @@ use alloc::vec;
 use alloc::string::String;
 use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
-// use sha2::{Sha256, Sha512, Digest}; // Temporarily disabled due to build issues
+use crate::crypto::{self, SignatureScheme};
 
 static SECURE_BOOT_ENABLED: AtomicBool = AtomicBool::new(false);
 static SECURE_BOOT_ENFORCED: AtomicBool = AtomicBool::new(false);
@@ -85,29 +85,10 @@ pub fn init(config: SecureBootConfig) -> bool {
 }
 
 fn check_uefi_secure_boot() -> bool {
-    // Check UEFI firmware interface for secure boot status
-    // This would interface with UEFI runtime services
-    
-    // For now, return true if we detect UEFI
-    if check_uefi_present() {
-        // Read SecureBoot variable from UEFI
-        let secure_boot_var = read_uefi_variable("SecureBoot");
-        return secure_boot_var == Some(1);
-    }
-    
-    false
-}
-
-fn check_uefi_present() -> bool {
-    // Check if UEFI runtime services are available
-    // This would check for UEFI system table
-    true // Placeholder
-}
-
-fn read_uefi_variable(name: &str) -> Option<u8> {
-    // Read UEFI variable
-    // This would interface with UEFI runtime services
-    Some(1) // Placeholder
+    // The UEFI loader reports whether firmware Secure Boot was on and it
+    // verified the kernel's signature under it; the firmware's own
+    // variables are gone with boot services
+    crate::boot::info().is_some_and(|info| info.secure_boot)
 }
 
 fn load_signature_database() -> bool {
@@ -305,47 +286,32 @@ fn is_revoked(signature: &Signature) -> bool {
 }
 
 fn verify_signature(image_data: &[u8], signature: &Signature) -> bool {
-    // Calculate hash of image
-    let hash = calculate_hash(image_data, &signature.algorithm);
-    
-    // Verify signature against hash
-    // This would use RSA or ECDSA verification
-    
-    true // Placeholder
+    let scheme = match signature_scheme(&signature.algorithm) {
+        Some(scheme) => scheme,
+        None => {
+            serial_println!("[SECURE_BOOT] Unsupported signature algorithm {:?}", signature.algorithm);
+            return false;
+        }
+    };
+
+    // Any key in db may have signed the image; malformed keys simply fail
+    let db_guard = SIGNATURE_DB.lock();
+    let db = match *db_guard {
+        Some(ref db) => db,
+        None => return false,
+    };
+    db.allowed_signatures.iter().any(|cert| {
+        crypto::verify_signature(scheme, &cert.public_key, image_data, &signature.data).unwrap_or(false)
+    })
 }
 
-fn calculate_hash(data: &[u8], algorithm: &SignatureAlgorithm) -> Vec<u8> {
+fn signature_scheme(algorithm: &SignatureAlgorithm) -> Option<SignatureScheme> {
     match algorithm {
-        SignatureAlgorithm::RsaSha256 | SignatureAlgorithm::EcdsaSha256 => {
-            // Temporarily disabled SHA256 due to build issues
-            // let mut hasher = Sha256::new();
-            // hasher.update(data);
-            // hasher.finalize().to_vec()
-            
-            // Simple checksum as temporary replacement
-            let mut hash = vec![0u8; 32];
-            for (i, &byte) in data.iter().enumerate() {
-                hash[i % 32] ^= byte;
-            }
-            hash
-        },
-        SignatureAlgorithm::RsaSha512 => {
-            // Temporarily disabled SHA512 due to build issues
-            // let mut hasher = Sha512::new();
-            // hasher.update(data);
-            // hasher.finalize().to_vec()
-            
-            // Simple checksum as temporary replacement
-            let mut hash = vec![0u8; 64];
-            for (i, &byte) in data.iter().enumerate() {
-                hash[i % 64] ^= byte;
-            }
-            hash
-        },
-        SignatureAlgorithm::EcdsaSha384 => {
-            // Would use SHA-384
-            vec![0; 48]
-        },
+        SignatureAlgorithm::RsaSha256 => Some(SignatureScheme::RsaPkcs1Sha256),
+        SignatureAlgorithm::RsaSha512 => Some(SignatureScheme::RsaPkcs1Sha512),
+        SignatureAlgorithm::EcdsaSha256 => Some(SignatureScheme::EcdsaP256Sha256),
+        // Needs P-384, which the crypto module does not implement
+        SignatureAlgorithm::EcdsaSha384 => None,
     }
 }
 
@@ -383,13 +349,9 @@ pub struct TrustedBootMeasurement {
 static MEASUREMENTS: Mutex<Vec<TrustedBootMeasurement>> = Mutex::new(Vec::new());
 
 pub fn measure_component(data: &[u8], description: String, pcr_index: u32) {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    let hash = hasher.finalize();
-    
     let measurement = TrustedBootMeasurement {
         pcr_index,
-        measurement: hash.into(),
+        measurement: crypto::sha256(data),
         description,
     };
     
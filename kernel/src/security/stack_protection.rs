@@ -261,28 +261,7 @@ unsafe fn enable_cet() {
     cr4 |= 1 << 23;
     asm!("mov cr4, {}", in(reg) cr4);
     
-    // Enable shadow stack in IA32_U_CET MSR
-    let cet_msr = 0x6A0;
-    let mut cet_value: u64 = 0;
-    
-    // Read current value
-    asm!(
-        "rdmsr",
-        in("ecx") cet_msr,
-        out("eax") cet_value,
-        out("edx") _,
-    );
-    
-    // Set SH_STK_EN (bit 0) and WR_SHSTK_EN (bit 1)
-    cet_value |= 0x3;
-    
-    // Write back
-    asm!(
-        "wrmsr",
-        in("ecx") cet_msr,
-        in("eax") cet_value as u32,
-        in("edx") (cet_value >> 32) as u32,
-    );
+    // IA32_U_CET is per process, loaded by the scheduler; see mitigations
 }
 
 fn create_shadow_stack() -> Result<VirtAddr, &'static str> {
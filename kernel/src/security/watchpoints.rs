@@ -0,0 +1,277 @@
+// Hardware watchpoints on critical kernel structures
+//
+// The integrity checker hashes kernel memory now and then, so it finds a
+// change only after the fact. The debug address registers catch a write as
+// it happens: each of the four watches 1, 2, 4 or 8 aligned bytes, and a
+// write there raises a debug exception right after the writing instruction,
+// with the writer's RIP in the frame. The handler reports the write and the
+// code that made it, and counts it; the audit log cannot be taken from an
+// exception, so the security event is logged from the main loop. A
+// watchpoint that panics stops the kernel there instead.
+//
+// Debug registers are per CPU. A change is loaded at once on the CPU making
+// it, and on every other CPU at its next timer tick.
+//
+// At boot the page fault gate of the IDT, the start of the system call entry
+// code and the security feature mask are watched, leaving one register for
+// other callers, as `security.watchpoints=` says.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::audit::{self, EventDetails, SecurityEvent, Severity};
+use crate::boot::cmdline::{Param, Value};
+use crate::serial_println;
+use crate::smp::MAX_CPUS;
+
+/// Debug address registers, DR0 to DR3
+pub const SLOTS: usize = 4;
+
+// DR7: an enable pair per slot from bit 0, of which the kernel uses the
+// global one, then a condition and a length per slot from bit 16
+const DR7_GLOBAL_EXACT: u64 = 1 << 9;
+const DR7_RESERVED: u64 = 1 << 10;
+const DR7_CONDITION_WRITE: u64 = 0b01;
+
+// DR6: which slots were hit, left for software to clear
+const DR6_HITS: u64 = 0xF;
+const DR6_CLEAR: u64 = 0xFFFF_0FF0;
+
+/// What a write to a watched address does besides being reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Log,
+    Panic,
+}
+
+/// What the boot-time watchpoints do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Log,
+    Panic,
+}
+
+impl Value for Mode {
+    const KIND: &'static str = "off|log|panic";
+
+    fn parse(text: &'static str) -> Option<Self> {
+        match text {
+            "off" => Some(Mode::Off),
+            "log" => Some(Mode::Log),
+            "panic" => Some(Mode::Panic),
+            _ => None,
+        }
+    }
+
+    fn show(&self) -> String {
+        format!("{:?}", self).to_lowercase()
+    }
+}
+
+static MODE: Param<Mode> =
+    Param::new("security.watchpoints", Mode::Log, "Watch the IDT, syscall entry and security flags for writes");
+
+#[derive(Debug, Clone)]
+pub struct Watchpoint {
+    pub name: String,
+    pub address: u64,
+    pub len: usize,
+    pub action: Action,
+    pub hits: u64,
+    /// The instruction after the last write seen
+    pub last_rip: u64,
+}
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; SLOTS]> = Mutex::new([None, None, None, None]);
+
+// What the exception handler reads, which cannot take the lock above
+static ADDRESSES: [AtomicU64; SLOTS] = [const { AtomicU64::new(0) }; SLOTS];
+static PANICS: [AtomicBool; SLOTS] = [const { AtomicBool::new(false) }; SLOTS];
+static HITS: [AtomicU64; SLOTS] = [const { AtomicU64::new(0) }; SLOTS];
+static LAST_RIP: [AtomicU64; SLOTS] = [const { AtomicU64::new(0) }; SLOTS];
+// Hits the main loop has yet to log
+static UNREPORTED: [AtomicU64; SLOTS] = [const { AtomicU64::new(0) }; SLOTS];
+static DR7: AtomicU64 = AtomicU64::new(0);
+
+// Bumped on each change; a CPU reloads its registers when the generation it
+// loaded is behind
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static LOADED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Arm the boot-time watchpoints
+pub fn init() {
+    let action = match MODE.get() {
+        Mode::Off => return,
+        Mode::Log => Action::Log,
+        Mode::Panic => Action::Panic,
+    };
+    // Debug registers watch aligned words, so the entry code's is watched
+    let syscall_entry = crate::arch::x86_64::fast_syscall::entry_address() & !7;
+    let critical = [
+        ("IDT page fault gate", crate::interrupts::idt_gate_address(14)),
+        ("syscall entry", syscall_entry),
+        ("security features", super::features_address()),
+    ];
+    for (name, address) in critical {
+        if let Err(error) = watch(name, address, 8, action) {
+            serial_println!("[WATCH] Cannot watch {}: {}", name, error);
+        }
+    }
+    serial_println!("[WATCH] Watching {} critical structures, {:?} on write", critical.len(), action);
+}
+
+/// Report writes to the `len` bytes at `address`, which must be 1, 2, 4 or
+/// 8 and divide the address; returns the slot used
+pub fn watch(name: &str, address: u64, len: usize, action: Action) -> Result<usize, &'static str> {
+    let length_bits = match len {
+        1 => 0b00,
+        2 => 0b01,
+        8 => 0b10,
+        4 => 0b11,
+        _ => return Err("length must be 1, 2, 4 or 8"),
+    };
+    if address % len as u64 != 0 {
+        return Err("address not aligned to its length");
+    }
+    let mut watchpoints = WATCHPOINTS.lock();
+    let slot = watchpoints.iter().position(Option::is_none).ok_or("all debug registers in use")?;
+    watchpoints[slot] = Some(Watchpoint {
+        name: String::from(name),
+        address,
+        len,
+        action,
+        hits: 0,
+        last_rip: 0,
+    });
+    ADDRESSES[slot].store(address, Ordering::Relaxed);
+    PANICS[slot].store(action == Action::Panic, Ordering::Relaxed);
+    HITS[slot].store(0, Ordering::Relaxed);
+    UNREPORTED[slot].store(0, Ordering::Relaxed);
+    let shift = 16 + 4 * slot;
+    let dr7 = DR7.load(Ordering::Relaxed) & !(0xF << shift);
+    let dr7 = dr7 | (1 << (2 * slot + 1)) | ((DR7_CONDITION_WRITE | length_bits << 2) << shift);
+    DR7.store(dr7, Ordering::Release);
+    drop(watchpoints);
+    changed();
+    Ok(slot)
+}
+
+/// Stop watching a slot
+pub fn unwatch(slot: usize) -> Result<(), &'static str> {
+    let mut watchpoints = WATCHPOINTS.lock();
+    watchpoints.get_mut(slot).and_then(Option::take).ok_or("no such watchpoint")?;
+    let dr7 = DR7.load(Ordering::Relaxed) & !(0b11 << (2 * slot)) & !(0xF << (16 + 4 * slot));
+    DR7.store(dr7, Ordering::Release);
+    drop(watchpoints);
+    changed();
+    Ok(())
+}
+
+/// The watchpoints in use, with their slots
+pub fn list() -> Vec<(usize, Watchpoint)> {
+    let watchpoints = WATCHPOINTS.lock();
+    watchpoints
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, watchpoint)| {
+            let mut watchpoint = watchpoint.clone()?;
+            watchpoint.hits = HITS[slot].load(Ordering::Relaxed);
+            watchpoint.last_rip = LAST_RIP[slot].load(Ordering::Relaxed);
+            Some((slot, watchpoint))
+        })
+        .collect()
+}
+
+fn changed() {
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    tick();
+}
+
+/// Load the current watchpoints if this CPU has yet to; from each timer tick
+pub fn tick() {
+    let cpu = crate::smp::current_cpu_id() as usize % MAX_CPUS;
+    let generation = GENERATION.load(Ordering::Acquire);
+    if LOADED[cpu].swap(generation, Ordering::AcqRel) == generation {
+        return;
+    }
+    let dr7 = DR7.load(Ordering::Acquire);
+    let enabled = if dr7 & 0xFF != 0 { dr7 | DR7_GLOBAL_EXACT | DR7_RESERVED } else { DR7_RESERVED };
+    // Off while the addresses change, so no half-made watchpoint fires
+    unsafe {
+        asm!("mov dr7, {}", in(reg) DR7_RESERVED, options(nomem, nostack));
+        asm!("mov dr0, {}", in(reg) ADDRESSES[0].load(Ordering::Relaxed), options(nomem, nostack));
+        asm!("mov dr1, {}", in(reg) ADDRESSES[1].load(Ordering::Relaxed), options(nomem, nostack));
+        asm!("mov dr2, {}", in(reg) ADDRESSES[2].load(Ordering::Relaxed), options(nomem, nostack));
+        asm!("mov dr3, {}", in(reg) ADDRESSES[3].load(Ordering::Relaxed), options(nomem, nostack));
+        asm!("mov dr6, {}", in(reg) DR6_CLEAR, options(nomem, nostack));
+        asm!("mov dr7, {}", in(reg) enabled, options(nomem, nostack));
+    }
+}
+
+/// The debug exception: report writes to watched addresses. No locks but
+/// a try for the name, as the write may have been made holding them
+pub fn handle_debug(frame: &InterruptStackFrame, frame_pointer: u64) {
+    let dr6: u64;
+    unsafe {
+        asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack));
+        asm!("mov dr6, {}", in(reg) DR6_CLEAR, options(nomem, nostack));
+    }
+    let armed = DR7.load(Ordering::Acquire);
+    let rip = frame.instruction_pointer.as_u64();
+    let mut ours = false;
+    for slot in (0..SLOTS).filter(|&slot| dr6 & DR6_HITS & (1 << slot) != 0 && armed & (0b11 << (2 * slot)) != 0) {
+        ours = true;
+        HITS[slot].fetch_add(1, Ordering::Relaxed);
+        UNREPORTED[slot].fetch_add(1, Ordering::Relaxed);
+        LAST_RIP[slot].store(rip, Ordering::Relaxed);
+        let name = WATCHPOINTS
+            .try_lock()
+            .and_then(|watchpoints| watchpoints[slot].as_ref().map(|watchpoint| watchpoint.name.clone()))
+            .unwrap_or_else(|| String::from("?"));
+        serial_println!(
+            "[WATCH] Write to {} at {:#x} by {:#x} {}",
+            name,
+            ADDRESSES[slot].load(Ordering::Relaxed),
+            rip,
+            crate::debug::symbols::format_address(rip)
+        );
+        if PANICS[slot].load(Ordering::Relaxed) {
+            crate::debug::oops("write to watched kernel memory", frame, frame_pointer);
+            panic!("Write to watched kernel memory: {}", name);
+        }
+    }
+    if !ours {
+        serial_println!("[WATCH] Debug exception at {:#x} with no watchpoint hit, DR6 {:#x}", rip, dr6);
+    }
+}
+
+/// Log the security events for writes since the last call; from the main
+/// loop
+pub fn poll() {
+    for slot in 0..SLOTS {
+        let hits = UNREPORTED[slot].swap(0, Ordering::Relaxed);
+        if hits == 0 {
+            continue;
+        }
+        let Some(watchpoint) = WATCHPOINTS.lock()[slot].clone() else {
+            continue;
+        };
+        let rip = LAST_RIP[slot].load(Ordering::Relaxed);
+        let mut details = EventDetails::new();
+        details.additional_info.push((String::from("address"), format!("{:#x}", watchpoint.address)));
+        details.additional_info.push((String::from("rip"), format!("{:#x}", rip)));
+        details.additional_info.push((String::from("writes"), format!("{}", hits)));
+        audit::log_event(
+            SecurityEvent::ProtectedMemoryWrite,
+            Severity::Critical,
+            &format!("Write to {} by {}", watchpoint.name, crate::debug::symbols::format_address(rip)),
+            details,
+        );
+    }
+}
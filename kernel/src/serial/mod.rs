@@ -0,0 +1,555 @@
+//! Serial ports
+//!
+//! COM1 to COM4 are probed at their legacy I/O addresses and memory-mapped
+//! UARTs can be registered by whoever finds them. Each port has a receive
+//! and a transmit ring filled and drained by its interrupt handler, or by
+//! `poll` while interrupts are off, so bytes are not lost between polls.
+//!
+//! COM1 carries the kernel console. The kernel log writes it polled, so it
+//! works from any context including panics. A port can be lent out
+//! besides: to the kernel debugger, which then has it to itself and
+//! silences the console on it, or to one process through `/dev/ttyS*`,
+//! which then gets the input the console would have read.
+
+pub mod ring;
+pub mod tty;
+pub mod uart;
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use ring::Ring;
+use uart::{Kind, Registers, Uart, IER_LINE, IER_MODEM, IER_RX, IER_TX, LSR_DATA_READY, LSR_OVERRUN, MSR_CTS};
+
+pub const MAX_PORTS: usize = 8;
+
+/// COM1 to COM4: I/O base and ISA interrupt line
+pub const COM_PORTS: [(u16, u8); 4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+
+/// The port the kernel console is on
+pub const CONSOLE_PORT: usize = 0;
+
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+const RX_SIZE: usize = 4096;
+const TX_SIZE: usize = 4096;
+// With flow control, RTS drops when the receive ring is this full and rises
+// again once it has drained below the low mark
+const RX_HIGH_WATER: usize = RX_SIZE * 3 / 4;
+const RX_LOW_WATER: usize = RX_SIZE / 4;
+// A chip that never stops reporting work is left alone after this many rounds
+const MAX_SERVICE_ROUNDS: usize = 64;
+// What an empty I/O address reads as
+const NO_DEVICE: u8 = 0xFF;
+
+// Owner encoding: free, the debugger, the Bluetooth stack, or a process id
+// plus one
+const FREE: u64 = 0;
+const DEBUGGER: u64 = u64::MAX;
+const BLUETOOTH: u64 = u64::MAX - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    NoDevice,
+    /// Lent to someone else
+    Busy,
+    /// Every slot has a port
+    NoSlot,
+}
+
+/// Who a port is lent to, besides the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Free,
+    Debugger,
+    /// Carrying HCI packets to a controller
+    Bluetooth,
+    Process(u32),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub interrupts: u64,
+    /// Bytes the chip lost before they were read
+    pub overruns: u64,
+    /// Bytes lost to a full receive ring
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    pub index: usize,
+    pub registers: Registers,
+    pub kind: Kind,
+    pub irq: Option<u8>,
+    pub baud: u32,
+    pub flow_control: bool,
+    pub owner: Owner,
+    pub rx_queued: usize,
+    pub tx_queued: usize,
+    pub stats: PortStats,
+}
+
+struct Hardware {
+    uart: Uart,
+    irq: Option<u8>,
+}
+
+pub struct SerialPort {
+    index: usize,
+    hardware: Once<Hardware>,
+    rx: Ring<RX_SIZE>,
+    tx: Ring<TX_SIZE>,
+    // Register access, which also makes the holder the receive ring's
+    // producer and the transmit ring's consumer. Taken in the interrupt
+    // handler, so holders keep interrupts off.
+    io: Mutex<()>,
+    // The other side of each ring
+    reader: Mutex<()>,
+    writer: Mutex<()>,
+    ier: AtomicU8,
+    flow_control: AtomicBool,
+    rts_held: AtomicBool,
+    owner: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    interrupts: AtomicU64,
+    overruns: AtomicU64,
+    dropped: AtomicU64,
+}
+
+static PORTS: [SerialPort; MAX_PORTS] = {
+    let mut index = 0;
+    let mut ports = [const { SerialPort::new(0) }; MAX_PORTS];
+    while index < MAX_PORTS {
+        ports[index].index = index;
+        index += 1;
+    }
+    ports
+};
+
+// Serializes handing out slots for registered UARTs
+static REGISTER: Mutex<()> = Mutex::new(());
+
+impl SerialPort {
+    const fn new(index: usize) -> Self {
+        SerialPort {
+            index,
+            hardware: Once::new(),
+            rx: Ring::new(),
+            tx: Ring::new(),
+            io: Mutex::new(()),
+            reader: Mutex::new(()),
+            writer: Mutex::new(()),
+            ier: AtomicU8::new(0),
+            flow_control: AtomicBool::new(false),
+            rts_held: AtomicBool::new(false),
+            owner: AtomicU64::new(FREE),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    fn uart(&self) -> &Uart {
+        &self.hardware.get().expect("serial port without hardware").uart
+    }
+
+    fn with_io<R>(&self, f: impl FnOnce(&Uart) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let _io = self.io.lock();
+            f(self.uart())
+        })
+    }
+
+    fn set_ier(&self, uart: &Uart, ier: u8) {
+        if self.ier.swap(ier, Ordering::Relaxed) != ier {
+            uart.set_interrupts(ier);
+        }
+    }
+
+    // Move what has arrived into the receive ring and what is queued into
+    // the transmitter. Called with `io` held.
+    fn service(&self, uart: &Uart) {
+        for _ in 0..RX_SIZE {
+            let status = uart.line_status();
+            // Nothing answers at the address
+            if status == NO_DEVICE {
+                return;
+            }
+            if status & LSR_OVERRUN != 0 {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            if status & LSR_DATA_READY == 0 {
+                break;
+            }
+            let byte = uart.receive();
+            if self.rx.push(byte) {
+                self.rx_bytes.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if self.flow_control.load(Ordering::Relaxed)
+            && self.rx.len() >= RX_HIGH_WATER
+            && !self.rts_held.swap(true, Ordering::Relaxed)
+        {
+            uart.set_flow_control(false, true);
+        }
+        // Reading the modem status also clears its interrupt
+        let modem = uart.modem_status();
+        self.transmit(uart, modem);
+    }
+
+    fn transmit(&self, uart: &Uart, modem: u8) {
+        let ier = self.ier.load(Ordering::Relaxed);
+        let held = self.flow_control.load(Ordering::Relaxed)
+            && !uart.kind().auto_flow_control()
+            && modem & MSR_CTS == 0;
+        if self.tx.is_empty() || held {
+            // A CTS change raises a modem status interrupt to resume on
+            self.set_ier(uart, ier & !IER_TX);
+            return;
+        }
+        if uart.can_transmit() {
+            for byte in (0..uart.kind().fifo_size()).map_while(|_| self.tx.pop()) {
+                uart.transmit(byte);
+                self.tx_bytes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // The transmitter interrupts once it has room for more; before
+        // interrupts are on, the queue is drained by polling
+        if ier & IER_RX != 0 {
+            self.set_ier(uart, ier | IER_TX);
+        }
+    }
+
+    /// Move bytes between the chip and the rings without waiting for an
+    /// interrupt
+    pub fn poll(&self) {
+        self.with_io(|uart| self.service(uart));
+    }
+
+    /// Take what has been received, returning how many bytes were copied
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let count = {
+            let _reader = self.reader.lock();
+            buffer.iter_mut().map_while(|slot| self.rx.pop().map(|byte| *slot = byte)).count()
+        };
+        if self.rts_held.load(Ordering::Relaxed) && self.rx.len() < RX_LOW_WATER {
+            self.with_io(|uart| {
+                if self.rts_held.swap(false, Ordering::Relaxed) {
+                    uart.set_flow_control(true, true);
+                }
+            });
+        }
+        count
+    }
+
+    /// Poll the chip, then take one byte; for callers running with
+    /// interrupts off
+    pub fn read_polled(&self) -> Option<u8> {
+        self.poll();
+        let mut byte = [0];
+        (self.read(&mut byte) == 1).then_some(byte[0])
+    }
+
+    /// Queue bytes for sending, returning how many fitted
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        let queued = {
+            let _writer = self.writer.lock();
+            self.tx.push_slice(bytes)
+        };
+        self.poll();
+        queued
+    }
+
+    /// Send bytes straight to the chip, waiting for each to go. Anything
+    /// queued is sent first.
+    pub fn write_polled(&self, bytes: &[u8]) {
+        self.with_io(|uart| {
+            while let Some(byte) = self.tx.pop() {
+                uart.send_polled(byte);
+            }
+            for &byte in bytes {
+                uart.send_polled(byte);
+            }
+        });
+    }
+
+    /// Drop what has been received and not read
+    pub fn flush_input(&self) {
+        let _reader = self.reader.lock();
+        self.rx.clear();
+    }
+
+    /// Drop what is queued and not yet sent
+    pub fn flush_output(&self) {
+        self.with_io(|_| while self.tx.pop().is_some() {});
+    }
+
+    pub fn rx_queued(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn tx_queued(&self) -> usize {
+        self.tx.len()
+    }
+
+    pub fn set_baud(&self, baud: u32) {
+        self.with_io(|uart| uart.set_baud(baud));
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.with_io(|uart| uart.baud())
+    }
+
+    /// RTS/CTS flow control: the chip's own where it has it, otherwise
+    /// RTS follows the receive ring and sending waits for CTS
+    pub fn set_flow_control(&self, enable: bool) {
+        self.flow_control.store(enable, Ordering::Relaxed);
+        self.with_io(|uart| {
+            self.rts_held.store(false, Ordering::Relaxed);
+            uart.set_flow_control(true, enable);
+            self.service(uart);
+        });
+    }
+
+    pub fn flow_control(&self) -> bool {
+        self.flow_control.load(Ordering::Relaxed)
+    }
+
+    /// Modem control and status registers
+    pub fn modem_lines(&self) -> (u8, u8) {
+        self.with_io(|uart| (uart.modem_control(), uart.modem_status()))
+    }
+
+    pub fn owner(&self) -> Owner {
+        match self.owner.load(Ordering::Acquire) {
+            FREE => Owner::Free,
+            DEBUGGER => Owner::Debugger,
+            BLUETOOTH => Owner::Bluetooth,
+            pid => Owner::Process((pid - 1) as u32),
+        }
+    }
+
+    /// Lend the port out; a process that already has it may claim it again
+    pub fn claim(&self, owner: Owner) -> Result<(), SerialError> {
+        let code = match owner {
+            Owner::Free => return Ok(()),
+            Owner::Debugger => DEBUGGER,
+            Owner::Bluetooth => BLUETOOTH,
+            Owner::Process(pid) => pid as u64 + 1,
+        };
+        match self.owner.compare_exchange(FREE, code, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => Ok(()),
+            Err(current) if current == code => Ok(()),
+            Err(_) => Err(SerialError::Busy),
+        }
+    }
+
+    /// Give the port back, if `owner` has it
+    pub fn release(&self, owner: Owner) {
+        if self.owner() == owner {
+            self.owner.store(FREE, Ordering::Release);
+        }
+    }
+
+    pub fn info(&self) -> PortInfo {
+        let hardware = self.hardware.get().expect("serial port without hardware");
+        PortInfo {
+            index: self.index,
+            registers: hardware.uart.registers(),
+            kind: hardware.uart.kind(),
+            irq: hardware.irq,
+            baud: self.baud(),
+            flow_control: self.flow_control(),
+            owner: self.owner(),
+            rx_queued: self.rx_queued(),
+            tx_queued: self.tx_queued(),
+            stats: PortStats {
+                rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+                tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+                interrupts: self.interrupts.load(Ordering::Relaxed),
+                overruns: self.overruns.load(Ordering::Relaxed),
+                dropped: self.dropped.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    // Set the chip up, once
+    fn attach(&self, mut uart: Uart, irq: Option<u8>) -> &Self {
+        self.hardware.call_once(|| {
+            uart.init(DEFAULT_BAUD);
+            Hardware { uart, irq }
+        });
+        self
+    }
+
+    // Let the chip interrupt when data arrives, the line or modem status
+    // changes, or, while there is something to send, the transmitter empties
+    fn enable_interrupts(&self) {
+        if let Some(irq) = self.hardware.get().and_then(|hardware| hardware.irq) {
+            self.with_io(|uart| self.set_ier(uart, IER_RX | IER_LINE | IER_MODEM));
+            crate::power::idle::allow_wakeup(irq);
+        }
+    }
+}
+
+/// A port that has hardware behind it
+pub fn port(index: usize) -> Option<&'static SerialPort> {
+    PORTS.get(index).filter(|port| port.hardware.get().is_some())
+}
+
+pub fn ports() -> impl Iterator<Item = &'static SerialPort> {
+    PORTS.iter().filter(|port| port.hardware.get().is_some())
+}
+
+// COM1, set up on first use so the console works from the first message
+fn console() -> &'static SerialPort {
+    let (base, irq) = COM_PORTS[CONSOLE_PORT];
+    PORTS[CONSOLE_PORT].attach(Uart::new(Registers::Port(base)), Some(irq))
+}
+
+/// Probe COM2 to COM4 and let every port found interrupt
+pub fn init() {
+    console().enable_interrupts();
+    for (index, &(base, irq)) in COM_PORTS.iter().enumerate().skip(1) {
+        let uart = Uart::new(Registers::Port(base));
+        if uart.probe() {
+            PORTS[index].attach(uart, Some(irq)).enable_interrupts();
+        }
+    }
+    for port in ports() {
+        let info = port.info();
+        crate::serial_println!(
+            "[SERIAL] ttyS{}: {:x?} {} irq {:?}",
+            info.index,
+            info.registers,
+            info.kind,
+            info.irq
+        );
+    }
+}
+
+/// Binds the device model's `serial8250` platform devices to the ports
+/// `init` found. The ports are set up before there is a device model, as
+/// the console is wanted from the first message.
+pub static PLATFORM_DRIVER: PlatformDriver = PlatformDriver;
+
+pub struct PlatformDriver;
+
+impl Driver for PlatformDriver {
+    fn name(&self) -> &'static str {
+        "serial8250"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Platform
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[Match::Platform("serial8250")]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        match device.ident {
+            Ident::Platform { instance, .. } if port(instance as usize).is_some() => Ok(()),
+            _ => Err(ProbeError::NoDevice),
+        }
+    }
+}
+
+/// Add a memory-mapped UART whose registers are `1 << shift` bytes apart,
+/// returning its port number. Without an IRQ it is serviced by `poll`.
+pub fn register_mmio(base: u64, shift: u8, irq: Option<u8>) -> Result<usize, SerialError> {
+    let uart = Uart::new(Registers::Mmio { base, shift });
+    if !uart.probe() {
+        return Err(SerialError::NoDevice);
+    }
+    let _register = REGISTER.lock();
+    let port = PORTS[COM_PORTS.len()..]
+        .iter()
+        .find(|port| port.hardware.get().is_none())
+        .ok_or(SerialError::NoSlot)?;
+    port.attach(uart, irq).enable_interrupts();
+    Ok(port.index)
+}
+
+/// Service every port on a legacy interrupt line
+pub fn handle_irq(irq: u8) {
+    for port in ports().filter(|port| port.hardware.get().is_some_and(|hardware| hardware.irq == Some(irq))) {
+        port.interrupts.fetch_add(1, Ordering::Relaxed);
+        let _io = port.io.lock();
+        let uart = port.uart();
+        // The line is edge-triggered: it only fires again once the chip
+        // has nothing left pending
+        for _ in 0..MAX_SERVICE_ROUNDS {
+            port.service(uart);
+            if !uart.interrupt_pending() {
+                break;
+            }
+        }
+    }
+}
+
+/// Service every port, for when interrupts are off
+pub fn poll() {
+    for port in ports() {
+        port.poll();
+    }
+}
+
+/// Make the console port usable from an NMI handler or the panic path. A
+/// CPU interrupted in the middle of printing never releases the port, so
+/// once other CPUs have had time to finish it is taken anyway.
+pub fn unlock_for_nmi() {
+    let console = console();
+    for _ in 0..10_000_000 {
+        if !console.io.is_locked() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { console.io.force_unlock() };
+}
+
+/// Console input, None while the console port is lent out
+pub fn read_byte() -> Option<u8> {
+    let console = console();
+    if console.owner() != Owner::Free {
+        return None;
+    }
+    console.read_polled()
+}
+
+/// Send one byte of console output, bypassing the kernel log
+pub fn write_byte(byte: u8) {
+    console_write(&[byte]);
+}
+
+/// Kernel log output, dropped while the debugger or a Bluetooth controller
+/// has the console port
+pub fn console_write(bytes: &[u8]) {
+    let console = console();
+    if !matches!(console.owner(), Owner::Debugger | Owner::Bluetooth) {
+        console.write_polled(bytes);
+    }
+}
+
+/// Every port, for the shell
+pub fn port_info() -> Vec<PortInfo> {
+    ports().map(SerialPort::info).collect()
+}
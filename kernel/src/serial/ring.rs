@@ -0,0 +1,76 @@
+//! Byte ring between an interrupt handler and the rest of the kernel
+//!
+//! One side only ever pushes and the other only pops, so neither needs a
+//! lock against the other: the producer publishes a byte by moving `head`
+//! past it and the consumer frees a slot by moving `tail`. Each side must
+//! still be serialized on its own; the driver keeps one lock per side,
+//! never the same one on both.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+pub struct Ring<const N: usize> {
+    buffer: [AtomicU8; N],
+    // Free-running counts of bytes pushed and popped; their difference is
+    // the fill level
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> Ring<N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two());
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        Ring { buffer: [const { AtomicU8::new(0) }; N], head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn free(&self) -> usize {
+        N - self.len()
+    }
+
+    /// Producer side; false if the ring is full
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) == N {
+            return false;
+        }
+        self.buffer[head & Self::MASK].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Producer side; how many of `bytes` fit
+    pub fn push_slice(&self, bytes: &[u8]) -> usize {
+        bytes.iter().take_while(|&&byte| self.push(byte)).count()
+    }
+
+    /// Consumer side
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.buffer[tail & Self::MASK].load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Consumer side; drop everything queued
+    pub fn clear(&self) {
+        self.tail.store(self.head.load(Ordering::Acquire), Ordering::Release);
+    }
+}
@@ -0,0 +1,337 @@
+//! /dev/ttyS*, /dev/ttyUSB* and /dev/ttyACM* character devices
+//!
+//! `ttyS` are the UARTs, `ttyUSB` USB serial adapters with a vendor
+//! protocol, and `ttyACM` those that follow CDC-ACM; see `usb::serial`.
+//! Each is a `TtyPort`.
+//!
+//! Opening a port lends it to the calling process until the process closes
+//! its last handle on it or exits; other processes and the kernel debugger
+//! get EBUSY meanwhile. The console keeps writing to COM1 while a process
+//! has it open but stops reading from it, so input goes to the process.
+//! A USB port that is unplugged while open fails every call with EIO.
+//!
+//! Reads and writes never block, as a process cannot yet be put to sleep on
+//! a port: a read returns what has arrived and a write queues what fits,
+//! failing with EAGAIN when there is nothing to read or no room at all.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::{Owner, SerialError, SerialPort};
+use crate::memory::userspace::validate_user_buffer;
+use crate::sync::Mutex;
+use crate::syscall::{EAGAIN, EBADF, EBUSY, EFAULT, EINVAL, EIO, EMFILE, ENODEV, ENOENT, ENOTTY};
+
+pub const DEVICE_PREFIX: &str = "/dev/ttyS";
+
+pub const TCFLSH: usize = 0x540B;
+pub const TIOCOUTQ: usize = 0x5411;
+pub const TIOCMGET: usize = 0x5415;
+pub const FIONREAD: usize = 0x541B;
+// Not Linux's: there is no termios here, only speed and flow control
+pub const TTY_GET_CONFIG: usize = 0x5480;
+pub const TTY_SET_CONFIG: usize = 0x5481;
+
+pub const TCIFLUSH: usize = 0;
+pub const TCOFLUSH: usize = 1;
+pub const TCIOFLUSH: usize = 2;
+
+pub const TIOCM_DTR: u32 = 0x002;
+pub const TIOCM_RTS: u32 = 0x004;
+pub const TIOCM_CTS: u32 = 0x020;
+pub const TIOCM_CAR: u32 = 0x040;
+pub const TIOCM_RNG: u32 = 0x080;
+pub const TIOCM_DSR: u32 = 0x100;
+
+// Per-process limit on open ports
+const MAX_HANDLES: usize = 16;
+
+// /dev/kvm numbers its handles from 3 up to 258, and there is no shared
+// descriptor table yet, so ports are numbered clear of that
+const FIRST_FD: usize = 512;
+
+/// TTY_GET_CONFIG and TTY_SET_CONFIG's argument
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// 1 for RTS/CTS
+    pub flow_control: u32,
+}
+
+/// A port behind a tty device
+pub trait TtyPort: Sync {
+    /// Move bytes between the hardware and the port's rings
+    fn poll(&self);
+
+    /// Take what has been received, returning how many bytes were copied
+    fn read(&self, buffer: &mut [u8]) -> usize;
+
+    /// Queue bytes for sending, returning how many fitted
+    fn write(&self, bytes: &[u8]) -> usize;
+
+    fn flush_input(&self);
+
+    fn flush_output(&self);
+
+    fn rx_queued(&self) -> usize;
+
+    fn tx_queued(&self) -> usize;
+
+    fn config(&self) -> SerialConfig;
+
+    /// Set the speed and flow control, failing with EINVAL for what the
+    /// port cannot do
+    fn configure(&self, config: SerialConfig) -> Result<(), usize>;
+
+    /// The modem lines, as TIOCM_* bits
+    fn modem_bits(&self) -> u32;
+
+    fn owner(&self) -> Owner;
+
+    fn claim(&self, owner: Owner) -> Result<(), SerialError>;
+
+    fn release(&self, owner: Owner);
+}
+
+impl TtyPort for SerialPort {
+    fn poll(&self) {
+        SerialPort::poll(self)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> usize {
+        SerialPort::read(self, buffer)
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        SerialPort::write(self, bytes)
+    }
+
+    fn flush_input(&self) {
+        SerialPort::flush_input(self)
+    }
+
+    fn flush_output(&self) {
+        SerialPort::flush_output(self)
+    }
+
+    fn rx_queued(&self) -> usize {
+        SerialPort::rx_queued(self)
+    }
+
+    fn tx_queued(&self) -> usize {
+        SerialPort::tx_queued(self)
+    }
+
+    fn config(&self) -> SerialConfig {
+        SerialConfig { baud: self.baud(), flow_control: self.flow_control() as u32 }
+    }
+
+    fn configure(&self, config: SerialConfig) -> Result<(), usize> {
+        if config.baud > super::uart::BASE_BAUD {
+            return Err(EINVAL);
+        }
+        self.set_baud(config.baud);
+        self.set_flow_control(config.flow_control != 0);
+        Ok(())
+    }
+
+    fn modem_bits(&self) -> u32 {
+        let (control, status) = self.modem_lines();
+        modem_bits(control, status)
+    }
+
+    fn owner(&self) -> Owner {
+        SerialPort::owner(self)
+    }
+
+    fn claim(&self, owner: Owner) -> Result<(), SerialError> {
+        SerialPort::claim(self, owner)
+    }
+
+    fn release(&self, owner: Owner) {
+        SerialPort::release(self, owner)
+    }
+}
+
+fn uart(index: usize) -> Option<&'static dyn TtyPort> {
+    super::port(index).map(|port| port as &dyn TtyPort)
+}
+
+// Device name prefixes, and each one's ports by number
+const FAMILIES: [(&str, fn(usize) -> Option<&'static dyn TtyPort>); 3] = [
+    (DEVICE_PREFIX, uart),
+    (crate::usb::serial::USB_PREFIX, crate::usb::serial::usb_port),
+    (crate::usb::serial::ACM_PREFIX, crate::usb::serial::acm_port),
+];
+
+// A port: which family, and its number in it
+type Tty = (usize, usize);
+
+fn lookup((family, index): Tty) -> Option<&'static dyn TtyPort> {
+    FAMILIES[family].1(index)
+}
+
+// Port by (process, fd)
+static HANDLES: Mutex<BTreeMap<(u32, usize), Tty>> = Mutex::new(BTreeMap::new());
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+// EBADF if `fd` is not a port the caller has open, EIO if the port has
+// gone since
+fn port_of(fd: usize) -> Result<&'static dyn TtyPort, usize> {
+    let tty = *HANDLES.lock().get(&(current_pid(), fd)).ok_or(EBADF)?;
+    lookup(tty).ok_or(EIO)
+}
+
+/// Whether `fd` is a port the caller has open
+pub fn owns(fd: usize) -> bool {
+    HANDLES.lock().contains_key(&(current_pid(), fd))
+}
+
+/// Open `/dev/ttyS<n>`, `/dev/ttyUSB<n>` or `/dev/ttyACM<n>`; None if
+/// `path` is not a serial device
+pub fn open(path: &str) -> Option<Result<usize, usize>> {
+    FAMILIES
+        .iter()
+        .enumerate()
+        .find_map(|(family, (prefix, _))| Some((family, path.strip_prefix(prefix)?)))
+        .map(|(family, index)| open_index(family, index))
+}
+
+fn open_index(family: usize, index: &str) -> Result<usize, usize> {
+    let index: usize = index.parse().map_err(|_| ENOENT)?;
+    let port = lookup((family, index)).ok_or(ENODEV)?;
+    let pid = current_pid();
+
+    let mut handles = HANDLES.lock();
+    let used: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    if used.len() >= MAX_HANDLES {
+        return Err(EMFILE);
+    }
+    let first_open = port.owner() != Owner::Process(pid);
+    port.claim(Owner::Process(pid)).map_err(|error| match error {
+        SerialError::Busy => EBUSY,
+        _ => ENODEV,
+    })?;
+    if first_open {
+        // Don't hand over what was typed at the console
+        port.flush_input();
+    }
+    let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+    handles.insert((pid, fd), (family, index));
+    Ok(fd)
+}
+
+// Give ports back once `pid` has no handle left on them
+fn release_unused(handles: &BTreeMap<(u32, usize), Tty>, pid: u32, ttys: &[Tty]) {
+    for &tty in ttys {
+        let still_open = handles.range((pid, 0)..=(pid, usize::MAX)).any(|(_, &open)| open == tty);
+        if let (false, Some(port)) = (still_open, lookup(tty)) {
+            port.release(Owner::Process(pid));
+        }
+    }
+}
+
+/// Close a handle; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let pid = current_pid();
+    let mut handles = HANDLES.lock();
+    let Some(tty) = handles.remove(&(pid, fd)) else {
+        return false;
+    };
+    release_unused(&handles, pid, &[tty]);
+    true
+}
+
+/// Close every port an exiting process has open
+pub fn release_process(pid: u32) {
+    let mut handles = HANDLES.lock();
+    let fds: Vec<usize> = handles.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+    let ttys: Vec<Tty> = fds.into_iter().filter_map(|fd| handles.remove(&(pid, fd))).collect();
+    release_unused(&handles, pid, &ttys);
+}
+
+pub fn read(fd: usize, buffer: &mut [u8]) -> Result<usize, usize> {
+    let port = port_of(fd)?;
+    port.poll();
+    match port.read(buffer) {
+        0 if !buffer.is_empty() => Err(EAGAIN),
+        count => Ok(count),
+    }
+}
+
+pub fn write(fd: usize, bytes: &[u8]) -> Result<usize, usize> {
+    let port = port_of(fd)?;
+    match port.write(bytes) {
+        0 if !bytes.is_empty() => Err(EAGAIN),
+        count => Ok(count),
+    }
+}
+
+fn write_user<T: Copy>(addr: usize, value: &T) -> Result<(), usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    unsafe { (addr as *mut T).write_unaligned(*value) };
+    Ok(())
+}
+
+fn read_user<T: Copy>(addr: usize) -> Result<T, usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { (addr as *const T).read_unaligned() })
+}
+
+fn modem_bits(control: u8, status: u8) -> u32 {
+    use super::uart::{MCR_DTR, MCR_RTS, MSR_CTS, MSR_DCD, MSR_DSR, MSR_RI};
+
+    [
+        (control & MCR_DTR, TIOCM_DTR),
+        (control & MCR_RTS, TIOCM_RTS),
+        (status & MSR_CTS, TIOCM_CTS),
+        (status & MSR_DCD, TIOCM_CAR),
+        (status & MSR_RI, TIOCM_RNG),
+        (status & MSR_DSR, TIOCM_DSR),
+    ]
+    .iter()
+    .filter(|(set, _)| *set != 0)
+    .fold(0, |bits, (_, bit)| bits | bit)
+}
+
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, usize> {
+    let port = port_of(fd)?;
+    match request {
+        FIONREAD => {
+            port.poll();
+            write_user(arg, &(port.rx_queued() as i32))?;
+        }
+        TIOCOUTQ => write_user(arg, &(port.tx_queued() as i32))?,
+        TIOCMGET => write_user(arg, &port.modem_bits())?,
+        TCFLSH => match arg {
+            TCIFLUSH => port.flush_input(),
+            TCOFLUSH => port.flush_output(),
+            TCIOFLUSH => {
+                port.flush_input();
+                port.flush_output();
+            }
+            _ => return Err(EINVAL),
+        },
+        TTY_GET_CONFIG => write_user(arg, &port.config())?,
+        TTY_SET_CONFIG => {
+            let config: SerialConfig = read_user(arg)?;
+            if config.baud == 0 || config.flow_control > 1 {
+                return Err(EINVAL);
+            }
+            port.configure(config)?;
+        }
+        _ => return Err(ENOTTY),
+    }
+    Ok(0)
+}
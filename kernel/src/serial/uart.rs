@@ -0,0 +1,296 @@
+//! 8250-family UART registers
+//!
+//! The same register block sits behind I/O ports on the legacy COM ports
+//! and in memory on PCI cards and SoC UARTs, where registers may be spaced
+//! wider than a byte apart. The chip is identified by how its FIFO control
+//! register behaves: no FIFO (8250/16450), a broken one (early 16550), 16
+//! bytes (16550A) or 64 bytes with automatic RTS/CTS (16750).
+
+use core::fmt;
+
+use x86_64::instructions::port::Port;
+
+use crate::memory::PHYS_MEM_OFFSET;
+
+// Registers, by index
+const RBR_THR: u8 = 0;
+const IER: u8 = 1;
+const IIR_FCR: u8 = 2;
+const LCR: u8 = 3;
+const MCR: u8 = 4;
+const LSR: u8 = 5;
+const MSR: u8 = 6;
+const SCR: u8 = 7;
+// With LCR_DLAB set
+const DLL: u8 = 0;
+const DLM: u8 = 1;
+
+pub const IER_RX: u8 = 1 << 0;
+pub const IER_TX: u8 = 1 << 1;
+pub const IER_LINE: u8 = 1 << 2;
+pub const IER_MODEM: u8 = 1 << 3;
+
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_FIFO_64: u8 = 1 << 5;
+const IIR_FIFO_MASK: u8 = 0xC0;
+const IIR_FIFO_WORKING: u8 = 0xC0;
+const IIR_FIFO_BROKEN: u8 = 0x80;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_64_BYTE: u8 = 1 << 5;
+// Receive interrupt after 8 bytes (14 of 16, 32 of 64 on a 16750), so a
+// burst costs a few interrupts without risking overrun
+const FCR_TRIGGER_8: u8 = 0x80;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+
+pub const MCR_DTR: u8 = 1 << 0;
+pub const MCR_RTS: u8 = 1 << 1;
+// Gates the interrupt line on PC serial ports
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const MCR_AUTO_FLOW: u8 = 1 << 5;
+
+pub const LSR_DATA_READY: u8 = 1 << 0;
+pub const LSR_OVERRUN: u8 = 1 << 1;
+pub const LSR_PARITY: u8 = 1 << 2;
+pub const LSR_FRAMING: u8 = 1 << 3;
+pub const LSR_BREAK: u8 = 1 << 4;
+pub const LSR_THR_EMPTY: u8 = 1 << 5;
+
+pub const MSR_CTS: u8 = 1 << 4;
+pub const MSR_DSR: u8 = 1 << 5;
+pub const MSR_RI: u8 = 1 << 6;
+pub const MSR_DCD: u8 = 1 << 7;
+
+/// Input clock of 1.8432 MHz divided by 16
+pub const BASE_BAUD: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registers {
+    Port(u16),
+    /// Physical address, registers `1 << shift` bytes apart
+    Mmio { base: u64, shift: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// No FIFO
+    Uart16450,
+    /// FIFO present but unusable
+    Uart16550,
+    Uart16550A,
+    Uart16750,
+}
+
+impl Kind {
+    /// Bytes the transmitter takes at a time
+    pub fn fifo_size(self) -> usize {
+        match self {
+            Kind::Uart16450 | Kind::Uart16550 => 1,
+            Kind::Uart16550A => 16,
+            Kind::Uart16750 => 64,
+        }
+    }
+
+    /// Whether the chip holds off sending and drives RTS by itself
+    pub fn auto_flow_control(self) -> bool {
+        self == Kind::Uart16750
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Uart16450 => "16450",
+            Kind::Uart16550 => "16550",
+            Kind::Uart16550A => "16550A",
+            Kind::Uart16750 => "16750",
+        })
+    }
+}
+
+/// One UART. Register access takes `&self`; callers serialize it.
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    registers: Registers,
+    kind: Kind,
+}
+
+impl Uart {
+    pub const fn new(registers: Registers) -> Self {
+        Uart { registers, kind: Kind::Uart16450 }
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.registers
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match self.registers {
+            Registers::Port(base) => unsafe { Port::<u8>::new(base + register as u16).read() },
+            Registers::Mmio { base, shift } => unsafe {
+                ((PHYS_MEM_OFFSET + base + ((register as u64) << shift)) as *const u8).read_volatile()
+            },
+        }
+    }
+
+    fn write(&self, register: u8, value: u8) {
+        match self.registers {
+            Registers::Port(base) => unsafe { Port::<u8>::new(base + register as u16).write(value) },
+            Registers::Mmio { base, shift } => unsafe {
+                ((PHYS_MEM_OFFSET + base + ((register as u64) << shift)) as *mut u8).write_volatile(value)
+            },
+        }
+    }
+
+    /// Whether a UART answers: the scratch register holds what is written
+    /// and, in loopback, modem outputs come back as inputs
+    pub fn probe(&self) -> bool {
+        for pattern in [0x55, 0xAA] {
+            self.write(SCR, pattern);
+            if self.read(SCR) != pattern {
+                return false;
+            }
+        }
+        let mcr = self.read(MCR);
+        // RTS and OUT2 loop back to CTS and DCD
+        self.write(MCR, MCR_LOOPBACK | MCR_RTS | MCR_OUT2);
+        let looped = self.read(MSR) & (MSR_CTS | MSR_DCD) == MSR_CTS | MSR_DCD;
+        self.write(MCR, MCR_LOOPBACK);
+        let cleared = self.read(MSR) & (MSR_CTS | MSR_DCD) == 0;
+        self.write(MCR, mcr);
+        looped && cleared
+    }
+
+    /// Reset to 8N1 at `baud` with interrupts off, find out what chip this
+    /// is and turn its FIFO on
+    pub fn init(&mut self, baud: u32) {
+        self.write(IER, 0);
+        self.set_baud(baud);
+        self.write(LCR, LCR_8N1);
+
+        self.write(IIR_FCR, FCR_ENABLE | FCR_64_BYTE);
+        let iir = self.read(IIR_FCR);
+        self.kind = match iir & IIR_FIFO_MASK {
+            IIR_FIFO_WORKING if iir & IIR_FIFO_64 != 0 => Kind::Uart16750,
+            IIR_FIFO_WORKING => Kind::Uart16550A,
+            IIR_FIFO_BROKEN => Kind::Uart16550,
+            _ => Kind::Uart16450,
+        };
+        match self.kind {
+            Kind::Uart16750 => {
+                self.write(IIR_FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_64_BYTE | FCR_TRIGGER_8)
+            }
+            Kind::Uart16550A => self.write(IIR_FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_8),
+            _ => self.write(IIR_FCR, 0),
+        }
+
+        self.write(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+        // Drop anything latched from before
+        self.read(LSR);
+        self.read(RBR_THR);
+        self.read(MSR);
+    }
+
+    /// Set the line speed; rates that do not divide 115200 are rounded
+    pub fn set_baud(&self, baud: u32) {
+        let divisor = (BASE_BAUD + baud / 2) / baud.max(1);
+        let divisor = divisor.clamp(1, u16::MAX as u32) as u16;
+        let lcr = self.read(LCR);
+        self.write(LCR, lcr | LCR_DLAB);
+        self.write(DLL, divisor as u8);
+        self.write(DLM, (divisor >> 8) as u8);
+        self.write(LCR, lcr & !LCR_DLAB);
+    }
+
+    pub fn baud(&self) -> u32 {
+        let lcr = self.read(LCR);
+        self.write(LCR, lcr | LCR_DLAB);
+        let divisor = self.read(DLL) as u32 | (self.read(DLM) as u32) << 8;
+        self.write(LCR, lcr);
+        BASE_BAUD / divisor.max(1)
+    }
+
+    pub fn set_interrupts(&self, mask: u8) {
+        self.write(IER, mask);
+    }
+
+    pub fn interrupts(&self) -> u8 {
+        self.read(IER)
+    }
+
+    /// Whether the chip has an interrupt waiting to be serviced
+    pub fn interrupt_pending(&self) -> bool {
+        self.read(IIR_FCR) & IIR_NONE_PENDING == 0
+    }
+
+    pub fn line_status(&self) -> u8 {
+        self.read(LSR)
+    }
+
+    pub fn modem_status(&self) -> u8 {
+        self.read(MSR)
+    }
+
+    pub fn modem_control(&self) -> u8 {
+        self.read(MCR)
+    }
+
+    /// Drive RTS, and let a 16750 drive it and watch CTS by itself
+    pub fn set_flow_control(&self, rts: bool, auto: bool) {
+        let mut mcr = self.read(MCR) & !(MCR_RTS | MCR_AUTO_FLOW);
+        if rts {
+            mcr |= MCR_RTS;
+        }
+        if auto && self.kind.auto_flow_control() {
+            mcr |= MCR_AUTO_FLOW;
+        }
+        self.write(MCR, mcr);
+    }
+
+    pub fn receive(&self) -> u8 {
+        self.read(RBR_THR)
+    }
+
+    /// Write to the transmitter, which must have room
+    pub fn transmit(&self, byte: u8) {
+        self.write(RBR_THR, byte);
+    }
+
+    pub fn can_transmit(&self) -> bool {
+        self.read(LSR) & LSR_THR_EMPTY != 0
+    }
+
+    /// Wait for room, then send, giving up on a transmitter that never
+    /// empties so a dead port cannot hang the console
+    pub fn send_polled(&self, byte: u8) {
+        for _ in 0..100_000 {
+            if self.can_transmit() {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.transmit(byte);
+    }
+
+    pub fn receive_polled(&self) -> Option<u8> {
+        (self.read(LSR) & LSR_DATA_READY != 0).then(|| self.read(RBR_THR))
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send_polled(byte);
+        }
+        Ok(())
+    }
+}
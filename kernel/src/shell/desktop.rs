@@ -214,9 +214,14 @@ impl Desktop {
             IconSize::ExtraLarge => 64,
         };
         
-        // Draw icon image
-        crate::println!("Drawing icon: {} at ({}, {}) size {}",
-            icon.item.name, icon.position.0, icon.position.1, size);
+        // Draw icon image: a program's own or an icon file's, else the
+        // generic one for its type
+        match crate::win32::resource::file_icon(&icon.item.path, size) {
+            Some(image) => crate::println!("Drawing icon: {} at ({}, {}) size {}, {}x{} from the file",
+                icon.item.name, icon.position.0, icon.position.1, size, image.image.width, image.image.height),
+            None => crate::println!("Drawing icon: {} at ({}, {}) size {}",
+                icon.item.name, icon.position.0, icon.position.1, size),
+        }
         
         // Draw selection highlight if selected
         if icon.selected {
@@ -116,6 +116,16 @@ unsafe fn setup_ap_boot_info() {
     asm!("mfence");
 }
 
+/// Start one application processor after boot, as when a CPU that never
+/// came up is brought online
+pub(super) fn start_cpu(cpu_id: u32, apic_id: u8) -> Result<bool, &'static str> {
+    unsafe {
+        copy_ap_boot_code();
+        setup_ap_boot_info();
+    }
+    start_single_ap(cpu_id, apic_id)
+}
+
 fn start_single_ap(cpu_id: u32, apic_id: u8) -> Result<bool, &'static str> {
     crate::serial_println!("SMP: Starting AP {} (APIC ID {})", cpu_id, apic_id);
     
@@ -161,6 +171,7 @@ fn start_single_ap(cpu_id: u32, apic_id: u8) -> Result<bool, &'static str> {
                 }
                 smp.mark_cpu_online(cpu_id);
             }
+            super::cpustat::online(cpu_id);
             
             crate::serial_println!("SMP: AP {} online", cpu_id);
             return Ok(true);
@@ -210,7 +221,13 @@ pub extern "C" fn ap_entry_point() {
             lapic.init();
         }
         
-        super::percpu::set_cpu_id(AP_CPU_COUNT.fetch_add(1, Ordering::SeqCst) + 1);
+        // Take the number the BSP gave this CPU, which need not follow the
+        // order APs came up in once CPUs are started later
+        AP_CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+        let boot_info = (PHYS_MEM_OFFSET + AP_BOOT_CODE_ADDR + 0x500) as *const ApBootInfo;
+        super::percpu::set_cpu_id(core::ptr::addr_of!((*boot_info).cpu_id).read_unaligned());
+        
+        crate::paravirt::init_cpu();
         
         crate::timer::init_ap_timer();
         
@@ -225,10 +242,10 @@ fn ap_idle_loop() -> ! {
     crate::serial_println!("AP {}: Entering idle loop", cpu_id);
     
     loop {
-        unsafe {
-            asm!("hlt");
-        }
+        crate::power::idle::enter(u64::MAX);
         
         crate::process::scheduler::schedule();
+
+        super::hotplug::park_if_offline();
     }
 }
\ No newline at end of file
@@ -0,0 +1,163 @@
+//! Per-CPU time accounting
+//!
+//! Each tick charges the time since the CPU's previous tick to whatever it
+//! interrupted: user mode, or another interrupt handler. Idle time is what
+//! the idle loop measures itself, and kernel time is the rest of the time
+//! the CPU has been online. A parked CPU accrues nothing.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::MAX_CPUS;
+use crate::time::clocksource::now_ns;
+
+// Not online
+const OFFLINE: u64 = u64::MAX;
+
+struct Counters {
+    user_ns: AtomicU64,
+    irq_ns: AtomicU64,
+    last_tick_ns: AtomicU64,
+    // Online time up to the last time the CPU went offline
+    online_ns: AtomicU64,
+    online_since_ns: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            user_ns: AtomicU64::new(0),
+            irq_ns: AtomicU64::new(0),
+            last_tick_ns: AtomicU64::new(0),
+            online_ns: AtomicU64::new(0),
+            online_since_ns: AtomicU64::new(OFFLINE),
+        }
+    }
+}
+
+static COUNTERS: [Counters; MAX_CPUS] = [const { Counters::new() }; MAX_CPUS];
+
+/// Where a CPU's time went, in nanoseconds
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user_ns: u64,
+    pub kernel_ns: u64,
+    pub irq_ns: u64,
+    pub idle_ns: u64,
+}
+
+impl CpuTimes {
+    pub fn total_ns(&self) -> u64 {
+        self.user_ns + self.kernel_ns + self.irq_ns + self.idle_ns
+    }
+
+    /// `part` as a percentage of the total
+    pub fn percent(&self, part: u64) -> u64 {
+        part * 100 / self.total_ns().max(1)
+    }
+
+    /// The time spent between an earlier reading and this one
+    pub fn since(&self, earlier: &CpuTimes) -> CpuTimes {
+        CpuTimes {
+            user_ns: self.user_ns.saturating_sub(earlier.user_ns),
+            kernel_ns: self.kernel_ns.saturating_sub(earlier.kernel_ns),
+            irq_ns: self.irq_ns.saturating_sub(earlier.irq_ns),
+            idle_ns: self.idle_ns.saturating_sub(earlier.idle_ns),
+        }
+    }
+}
+
+fn counters(cpu: u32) -> &'static Counters {
+    &COUNTERS[(cpu as usize).min(MAX_CPUS - 1)]
+}
+
+/// Charge the time since this CPU's last tick to user mode or to an
+/// interrupt handler, whichever the tick interrupted
+pub fn tick(user: bool, in_irq: bool) {
+    let counters = counters(super::current_cpu_id());
+    let now = now_ns();
+    let elapsed = now.saturating_sub(counters.last_tick_ns.swap(now, Ordering::Relaxed));
+    if user {
+        counters.user_ns.fetch_add(elapsed, Ordering::Relaxed);
+    } else if in_irq {
+        counters.irq_ns.fetch_add(elapsed, Ordering::Relaxed);
+    }
+}
+
+/// Start counting `cpu`'s time
+pub fn online(cpu: u32) {
+    let counters = counters(cpu);
+    let now = now_ns();
+    counters.last_tick_ns.store(now, Ordering::Relaxed);
+    counters.online_since_ns.store(now, Ordering::Relaxed);
+}
+
+/// Stop counting `cpu`'s time
+pub fn offline(cpu: u32) {
+    let counters = counters(cpu);
+    let since = counters.online_since_ns.swap(OFFLINE, Ordering::Relaxed);
+    if since != OFFLINE {
+        counters.online_ns.fetch_add(now_ns().saturating_sub(since), Ordering::Relaxed);
+    }
+}
+
+pub fn times(cpu: u32) -> CpuTimes {
+    let counters = counters(cpu);
+    let since = counters.online_since_ns.load(Ordering::Relaxed);
+    let mut online = counters.online_ns.load(Ordering::Relaxed);
+    if since != OFFLINE {
+        online += now_ns().saturating_sub(since);
+    }
+    let user_ns = counters.user_ns.load(Ordering::Relaxed);
+    let irq_ns = counters.irq_ns.load(Ordering::Relaxed);
+    let idle_ns = crate::power::idle::stats(cpu as usize).idle_ns;
+    CpuTimes {
+        user_ns,
+        kernel_ns: online.saturating_sub(user_ns + irq_ns + idle_ns),
+        irq_ns,
+        idle_ns,
+    }
+}
+
+/// Every CPU's times, by CPU, including those now offline that ran before
+pub fn all() -> Vec<(u32, CpuTimes)> {
+    (0..MAX_CPUS as u32)
+        .filter(|&cpu| {
+            let counters = counters(cpu);
+            counters.online_since_ns.load(Ordering::Relaxed) != OFFLINE || counters.online_ns.load(Ordering::Relaxed) > 0
+        })
+        .map(|cpu| (cpu, times(cpu)))
+        .collect()
+}
+
+// Linux counts /proc/stat in hundredths of a second
+const NS_PER_USER_TICK: u64 = 10_000_000;
+
+/// /proc/stat: user, nice, system, idle, iowait, irq and softirq time per
+/// CPU, with the nice, iowait and softirq columns always 0
+pub fn proc_stat() -> String {
+    let line = |name: &str, times: &CpuTimes| {
+        format!(
+            "{} {} 0 {} {} 0 {} 0\n",
+            name,
+            times.user_ns / NS_PER_USER_TICK,
+            times.kernel_ns / NS_PER_USER_TICK,
+            times.idle_ns / NS_PER_USER_TICK,
+            times.irq_ns / NS_PER_USER_TICK
+        )
+    };
+    let cpus = all();
+    let total = cpus.iter().fold(CpuTimes::default(), |sum, (_, times)| CpuTimes {
+        user_ns: sum.user_ns + times.user_ns,
+        kernel_ns: sum.kernel_ns + times.kernel_ns,
+        irq_ns: sum.irq_ns + times.irq_ns,
+        idle_ns: sum.idle_ns + times.idle_ns,
+    });
+    let mut text = line("cpu ", &total);
+    for (cpu, times) in &cpus {
+        text += &line(&format!("cpu{}", cpu), times);
+    }
+    text
+}
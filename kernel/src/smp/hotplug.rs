@@ -0,0 +1,110 @@
+//! CPU hotplug
+//!
+//! Taking a CPU offline parks it. The scheduler stops giving it threads and
+//! moves those queued on it to CPUs their affinity allows, or to any CPU if
+//! it allows none, and the interrupts routed to it go to the boot CPU. The
+//! CPU itself stops its tick and halts the next time it passes through its
+//! idle loop, and stays halted until brought back online. A CPU that never
+//! started, under nosmp or after a failed startup, is started afresh.
+//!
+//! The boot CPU cannot go offline: it takes the legacy interrupts and the
+//! HPET.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use super::{ipi, CpuState, MAX_CPUS, SMP_MANAGER};
+use crate::process::smp_scheduler::SMP_SCHEDULER;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    NoSuchCpu,
+    BootCpu,
+    AlreadyOnline,
+    AlreadyOffline,
+    /// The CPU did not answer its startup IPIs
+    StartFailed,
+}
+
+static PARKED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Whether `cpu` has been taken offline and should run nothing
+pub fn is_parked(cpu: u32) -> bool {
+    PARKED.get(cpu as usize).is_some_and(|parked| parked.load(Ordering::Acquire))
+}
+
+/// Take `cpu` offline. Returns how many threads moved off it.
+pub fn cpu_down(cpu: u32) -> Result<usize, HotplugError> {
+    let apic_id = {
+        let mut smp = SMP_MANAGER.lock();
+        let info = smp.get_cpu_mut(cpu).ok_or(HotplugError::NoSuchCpu)?;
+        if info.is_bsp {
+            return Err(HotplugError::BootCpu);
+        }
+        if info.state != CpuState::Online {
+            return Err(HotplugError::AlreadyOffline);
+        }
+        info.state = CpuState::Halted;
+        let apic_id = info.apic_id;
+        smp.mark_cpu_offline(cpu);
+        apic_id
+    };
+    PARKED[cpu as usize].store(true, Ordering::Release);
+
+    let moved = SMP_SCHEDULER.drain_cpu(cpu);
+    let boot_apic = SMP_MANAGER.lock().get_bsp().map_or(0, |bsp| bsp.apic_id);
+    let redirected = crate::acpi::apic::redirect_irqs(apic_id, boot_apic);
+    super::cpustat::offline(cpu);
+    // Out of the idle loop and into the parking one
+    ipi::send_ipi(apic_id, ipi::IPI_VECTOR_RESCHEDULE);
+
+    crate::pr_info!("CPU {} offline: {} threads moved, {} interrupts redirected", cpu, moved, redirected);
+    Ok(moved)
+}
+
+/// Bring `cpu` back online, or start it if it never ran
+pub fn cpu_up(cpu: u32) -> Result<(), HotplugError> {
+    let (state, apic_id) = {
+        let smp = SMP_MANAGER.lock();
+        let info = smp.get_cpu(cpu).ok_or(HotplugError::NoSuchCpu)?;
+        (info.state, info.apic_id)
+    };
+    match state {
+        CpuState::Online | CpuState::Booting => return Err(HotplugError::AlreadyOnline),
+        CpuState::Halted => {
+            {
+                let mut smp = SMP_MANAGER.lock();
+                if let Some(info) = smp.get_cpu_mut(cpu) {
+                    info.state = CpuState::Online;
+                }
+                smp.mark_cpu_online(cpu);
+            }
+            super::cpustat::online(cpu);
+            PARKED[cpu as usize].store(false, Ordering::Release);
+            ipi::send_ipi(apic_id, ipi::IPI_VECTOR_RESCHEDULE);
+        }
+        CpuState::Offline => {
+            if !super::ap_boot::start_cpu(cpu, apic_id).map_err(|_| HotplugError::StartFailed)? {
+                return Err(HotplugError::StartFailed);
+            }
+        }
+    }
+    crate::pr_info!("CPU {} online", cpu);
+    Ok(())
+}
+
+/// Halt this CPU while it is offline. Called from the AP idle loop.
+pub fn park_if_offline() {
+    let cpu = super::current_cpu_id();
+    if !is_parked(cpu) {
+        return;
+    }
+    crate::timer::stop_ap_timer();
+    // Only IPIs reach a parked CPU; the one that brings it back online
+    // ends the halt
+    while is_parked(cpu) {
+        interrupts::enable_and_hlt();
+    }
+    crate::timer::init_ap_timer();
+}
@@ -250,6 +250,7 @@ fn handle_tlb_flush_ipi() {
 
 fn handle_panic_ipi() {
     crate::serial_println!("CPU {}: Received panic IPI, halting", percpu::get_cpu_id());
+    crate::debug::kdump::save_cpu_state();
     loop {
         unsafe {
             core::arch::asm!("cli; hlt");
@@ -3,12 +3,16 @@ pub mod percpu;
 pub mod ipi;
 pub mod topology;
 pub mod numa;
+pub mod hotplug;
+pub mod cpustat;
 
 use alloc::vec::Vec;
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use spin::{Mutex, Once};
+use spin::Once;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use crate::acpi::apic::{LocalApicInfo, ApicInfo};
+use crate::boot::cmdline::Param;
 
 pub const MAX_CPUS: usize = 256;
 
@@ -121,6 +125,8 @@ lazy_static! {
 
 static BSP_INIT: Once = Once::new();
 
+static NOSMP: Param<bool> = Param::new("nosmp", false, "Run on the boot CPU only");
+
 pub fn init_bsp() {
     BSP_INIT.call_once(|| {
         use crate::cpu::get_cpu_id;
@@ -128,14 +134,24 @@ pub fn init_bsp() {
         let mut smp = SMP_MANAGER.lock();
         let bsp_info = CpuInfo::new(0, 0, true);
         smp.register_cpu(bsp_info);
+        cpustat::online(0);
+        crate::fs::procfs::register("stat", cpustat::proc_stat);
         
         topology::detect_topology();
         
         crate::serial_println!("SMP: BSP initialized (CPU 0)");
+        if NOSMP.get() {
+            crate::serial_println!("SMP: nosmp given, running on the BSP only");
+        }
     });
 }
 
 pub fn init_ap_cpus(apic_info: &ApicInfo) -> Result<(), &'static str> {
+    if NOSMP.get() {
+        crate::serial_println!("SMP: nosmp given, application processors left offline");
+        return Ok(());
+    }
+    
     let mut smp = SMP_MANAGER.lock();
     
     let mut cpu_id = 1;
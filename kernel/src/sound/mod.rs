@@ -219,6 +219,20 @@ impl AudioManager {
         Ok(())
     }
     
+    /// Whether a sound card was found to play on
+    pub fn has_output(&self) -> bool {
+        self.active_driver.is_some()
+    }
+    
+    /// Write samples in the default format straight to the playback
+    /// stream, starting it first if need be
+    pub fn write_playback(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        if !self.playback_thread_running.load(Ordering::Relaxed) {
+            self.start_playback()?;
+        }
+        self.playback_stream.as_mut().ok_or("No playback stream")?.write(data)
+    }
+    
     pub fn stop_playback(&mut self) -> Result<(), &'static str> {
         if let Some(ref mut stream) = self.playback_stream {
             stream.stop()?;
@@ -532,6 +546,15 @@ pub fn init() {
     });
 }
 
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Bring the sound cards up, once, for the first user that needs them
+pub fn ensure_init() {
+    if !INITIALIZED.swap(true, Ordering::SeqCst) {
+        init();
+    }
+}
+
 pub fn play_startup_sound() {
     // Play a simple startup chime
     let mut manager = AUDIO_MANAGER.lock();
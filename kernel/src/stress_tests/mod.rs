@@ -638,6 +638,7 @@ pub fn run_all_stress_tests() {
     println!("WARNING: These tests will stress system resources!\n");
     
     let mut runner = StressTestRunner::new();
+    let heap_checkpoint = crate::allocator::heap_debug::checkpoint();
     
     // Memory stress tests
     println!("\n[Memory Stress Tests]");
@@ -665,4 +666,12 @@ pub fn run_all_stress_tests() {
     
     // Display summary
     runner.summary();
+    
+    // With heap debugging built in, catch the corruption the tests simulate
+    // when it happens for real
+    if crate::allocator::heap_debug::enabled() {
+        let corrupted = crate::allocator::heap_debug::verify();
+        let leaked = crate::allocator::heap_debug::report_leaks(heap_checkpoint);
+        println!("Heap check: {} corrupted object(s), {} leaked object(s)", corrupted, leaked);
+    }
 }
\ No newline at end of file
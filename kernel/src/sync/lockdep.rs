@@ -0,0 +1,503 @@
+// Lock Dependency Validator
+// Every sync::Mutex belongs to a lock class, the source location of its
+// Mutex::new, so all locks created at one place (one per process, say) share
+// a class. Debug builds check each acquisition against the locks the CPU
+// already holds:
+//
+// - taking a lock the CPU already holds spins forever;
+// - taking B while holding A records the dependency A -> B. If B -> ... -> A
+//   was recorded earlier, two CPUs taking the locks in those orders deadlock;
+// - a class taken both inside an interrupt handler and elsewhere with
+//   interrupts enabled deadlocks when the interrupt arrives while it is held.
+//
+// The first violation is reported over serial with the acquisition sites of
+// every lock involved and the current call trace, then validation turns
+// itself off. Held locks are tracked per CPU: spin locks must not be held
+// across a context switch.
+#![cfg_attr(not(debug_assertions), allow(dead_code))]
+
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::GsBase;
+
+use crate::smp::MAX_CPUS;
+
+pub const MAX_CLASSES: usize = 512;
+pub const MAX_DEPENDENCIES: usize = 4096;
+const MAX_HELD: usize = 16;
+const WORDS: usize = MAX_CLASSES / 64;
+
+type Site = &'static Location<'static>;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Lock class of a mutex, registered on first acquisition
+pub struct LockClassKey {
+    site: Site,
+    class: AtomicU16, // index + 1, 0 until registered
+}
+
+impl LockClassKey {
+    pub const fn new(site: Site) -> Self {
+        Self {
+            site,
+            class: AtomicU16::new(0),
+        }
+    }
+}
+
+// Usage bits, with the first site of each kept in the class
+const USED_IN_IRQ: u8 = 1 << 0;
+const USED_IRQS_ENABLED: u8 = 1 << 1;
+
+#[derive(Clone, Copy)]
+struct Class {
+    site: Option<Site>,
+    irq_site: Option<Site>,
+    enabled_site: Option<Site>,
+}
+
+#[derive(Clone, Copy)]
+struct Dependency {
+    from: u16,
+    to: u16,
+    held_at: Option<Site>,
+    taken_at: Option<Site>,
+}
+
+struct Graph {
+    classes: [Class; MAX_CLASSES],
+    class_count: usize,
+    dependencies: [Dependency; MAX_DEPENDENCIES],
+    dependency_count: usize,
+    // Breadth-first search scratch space
+    parent: [u16; MAX_CLASSES],
+    queue: [u16; MAX_CLASSES],
+}
+
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+    classes: [Class { site: None, irq_site: None, enabled_site: None }; MAX_CLASSES],
+    class_count: 0,
+    dependencies: [Dependency { from: 0, to: 0, held_at: None, taken_at: None }; MAX_DEPENDENCIES],
+    dependency_count: 0,
+    parent: [0; MAX_CLASSES],
+    queue: [0; MAX_CLASSES],
+});
+
+// Read without the graph lock on every acquisition; only set while holding it
+static AFTER: [[AtomicU64; WORDS]; MAX_CLASSES] = [const { [const { AtomicU64::new(0) }; WORDS] }; MAX_CLASSES];
+static USAGE: [AtomicU8; MAX_CLASSES] = [const { AtomicU8::new(0) }; MAX_CLASSES];
+
+fn depends(from: usize, to: usize) -> bool {
+    AFTER[from][to / 64].load(Ordering::Acquire) & (1 << (to % 64)) != 0
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    lock: usize,
+    class: u16,
+    irq_depth: u8,
+    site: Option<Site>,
+}
+
+struct CpuState {
+    busy: bool,
+    irq_depth: u8,
+    depth: usize,
+    held: [Held; MAX_HELD],
+}
+
+struct PerCpu(UnsafeCell<CpuState>);
+
+// Each CPU only touches its own state, with interrupts disabled
+unsafe impl Sync for PerCpu {}
+
+static CPUS: [PerCpu; MAX_CPUS] = [const {
+    PerCpu(UnsafeCell::new(CpuState {
+        busy: false,
+        irq_depth: 0,
+        depth: 0,
+        held: [Held { lock: 0, class: 0, irq_depth: 0, site: None }; MAX_HELD],
+    }))
+}; MAX_CPUS];
+
+fn cpu_index() -> usize {
+    // The boot CPU runs without a per-CPU area, leaving GS at zero
+    if GsBase::read().as_u64() == 0 {
+        0
+    } else {
+        crate::smp::current_cpu_id() as usize % MAX_CPUS
+    }
+}
+
+fn this_cpu() -> &'static mut CpuState {
+    unsafe { &mut *CPUS[cpu_index()].0.get() }
+}
+
+pub fn init() {
+    if cfg!(debug_assertions) {
+        ENABLED.store(true, Ordering::Release);
+        crate::serial_println!(
+            "[LOCKDEP] Lock validator enabled ({} classes, {} dependencies)",
+            MAX_CLASSES,
+            MAX_DEPENDENCIES
+        );
+    }
+}
+
+/// Interrupt handlers call this on entry, so locks they take are known to
+/// be taken in interrupt context
+pub fn hardirq_enter() {
+    let cpu = this_cpu();
+    cpu.irq_depth = cpu.irq_depth.saturating_add(1);
+}
+
+pub fn hardirq_exit() {
+    let cpu = this_cpu();
+    cpu.irq_depth = cpu.irq_depth.saturating_sub(1);
+}
+
+/// Whether this CPU is in an interrupt handler that called `hardirq_enter`
+pub fn in_hardirq() -> bool {
+    this_cpu().irq_depth > 0
+}
+
+/// Validate taking `lock` at `site`. Blocking acquisitions are checked before
+/// they spin; try-locks, which cannot deadlock, are only recorded as held.
+pub fn acquire(lock: usize, key: &LockClassKey, site: Site, trylock: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let irqs_enabled = interrupts::are_enabled();
+    interrupts::without_interrupts(|| {
+        let cpu = this_cpu();
+        // Locks taken while reporting are not validated
+        if cpu.busy {
+            return;
+        }
+        cpu.busy = true;
+        validate(cpu, lock, key, site, trylock, irqs_enabled);
+        cpu.busy = false;
+    });
+}
+
+pub fn release(lock: usize) {
+    interrupts::without_interrupts(|| {
+        let cpu = this_cpu();
+        if cpu.busy {
+            return;
+        }
+        // Guards are usually dropped in reverse order, but not always
+        if let Some(index) = cpu.held[..cpu.depth].iter().rposition(|held| held.lock == lock) {
+            cpu.held.copy_within(index + 1..cpu.depth, index);
+            cpu.depth -= 1;
+        }
+    });
+}
+
+fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+fn validate(cpu: &mut CpuState, lock: usize, key: &LockClassKey, site: Site, trylock: bool, irqs_enabled: bool) {
+    if !trylock {
+        if let Some(held) = cpu.held[..cpu.depth].iter().find(|held| held.lock == lock) {
+            let held = *held;
+            disable();
+            report_recursion(cpu, &held, key, site);
+            return;
+        }
+    }
+
+    let class = match class_of(key) {
+        Some(class) => class,
+        None => {
+            disable();
+            crate::serial_println!("[LOCKDEP] Out of lock classes, validation turned off");
+            return;
+        }
+    };
+
+    if !trylock {
+        let usage = if cpu.irq_depth > 0 {
+            USED_IN_IRQ
+        } else if irqs_enabled {
+            USED_IRQS_ENABLED
+        } else {
+            0
+        };
+        if usage != 0 && USAGE[class].load(Ordering::Acquire) & usage == 0 && !mark_usage(cpu, class, usage, site) {
+            return;
+        }
+
+        // Only order against locks taken in the same context; an interrupt
+        // handler's locks do not nest inside those of the code it interrupted
+        for index in 0..cpu.depth {
+            let held = cpu.held[index];
+            let from = held.class as usize;
+            if held.irq_depth != cpu.irq_depth || from == class || depends(from, class) {
+                continue;
+            }
+            if !add_dependency(cpu, &held, class, site) {
+                return;
+            }
+        }
+    }
+
+    if cpu.depth == MAX_HELD {
+        disable();
+        crate::serial_println!("[LOCKDEP] More than {} locks held on one CPU, validation turned off", MAX_HELD);
+        return;
+    }
+    cpu.held[cpu.depth] = Held {
+        lock,
+        class: class as u16,
+        irq_depth: cpu.irq_depth,
+        site: Some(site),
+    };
+    cpu.depth += 1;
+}
+
+fn class_of(key: &LockClassKey) -> Option<usize> {
+    let cached = key.class.load(Ordering::Acquire);
+    if cached != 0 {
+        return Some(cached as usize - 1);
+    }
+
+    let mut graph = GRAPH.lock();
+    let count = graph.class_count;
+    let same = |other: Site| {
+        other.line() == key.site.line() && other.column() == key.site.column() && other.file() == key.site.file()
+    };
+    let class = match graph.classes[..count].iter().position(|class| class.site.is_some_and(same)) {
+        Some(class) => class,
+        None if count < MAX_CLASSES => {
+            graph.classes[count].site = Some(key.site);
+            graph.class_count += 1;
+            count
+        }
+        None => return None,
+    };
+    key.class.store(class as u16 + 1, Ordering::Release);
+    Some(class)
+}
+
+// Returns false after reporting an interrupt-safety violation
+fn mark_usage(cpu: &CpuState, class: usize, usage: u8, site: Site) -> bool {
+    let mut graph = GRAPH.lock();
+    let previous = USAGE[class].fetch_or(usage, Ordering::AcqRel);
+    if previous & usage == 0 {
+        if usage == USED_IN_IRQ {
+            graph.classes[class].irq_site = Some(site);
+        } else {
+            graph.classes[class].enabled_site = Some(site);
+        }
+    }
+
+    if (previous | usage) & (USED_IN_IRQ | USED_IRQS_ENABLED) != USED_IN_IRQ | USED_IRQS_ENABLED {
+        return true;
+    }
+    disable();
+    report_irq_inversion(cpu, &graph, class);
+    false
+}
+
+// Returns false after reporting a cycle or running out of space
+fn add_dependency(cpu: &CpuState, held: &Held, to: usize, site: Site) -> bool {
+    let mut graph = GRAPH.lock();
+    let from = held.class as usize;
+    if depends(from, to) {
+        return true;
+    }
+
+    if let Some(length) = find_path(&mut graph, to, from) {
+        disable();
+        report_cycle(cpu, &graph, held, to, site, length);
+        return false;
+    }
+
+    let count = graph.dependency_count;
+    if count == MAX_DEPENDENCIES {
+        disable();
+        crate::serial_println!("[LOCKDEP] Out of dependency entries, validation turned off");
+        return false;
+    }
+    graph.dependencies[count] = Dependency {
+        from: from as u16,
+        to: to as u16,
+        held_at: held.site,
+        taken_at: Some(site),
+    };
+    graph.dependency_count += 1;
+    AFTER[from][to / 64].fetch_or(1 << (to % 64), Ordering::Release);
+    true
+}
+
+// Breadth-first search for from -> ... -> to. On success the path is left in
+// graph.queue[..length], from first.
+fn find_path(graph: &mut Graph, from: usize, to: usize) -> Option<usize> {
+    let mut visited = [0u64; WORDS];
+    visited[from / 64] |= 1 << (from % 64);
+    graph.queue[0] = from as u16;
+    let (mut head, mut tail) = (0, 1);
+
+    while head < tail {
+        let class = graph.queue[head] as usize;
+        head += 1;
+        for word in 0..WORDS {
+            let mut bits = AFTER[class][word].load(Ordering::Acquire) & !visited[word];
+            while bits != 0 {
+                let next = word * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                visited[word] |= 1 << (next % 64);
+                graph.parent[next] = class as u16;
+                if next == to {
+                    return Some(unwind_path(graph, from, to));
+                }
+                graph.queue[tail] = next as u16;
+                tail += 1;
+            }
+        }
+    }
+    None
+}
+
+fn unwind_path(graph: &mut Graph, from: usize, to: usize) -> usize {
+    let mut length = 1;
+    let mut class = to;
+    while class != from {
+        class = graph.parent[class] as usize;
+        length += 1;
+    }
+
+    class = to;
+    for slot in (0..length).rev() {
+        graph.queue[slot] = class as u16;
+        class = graph.parent[class] as usize;
+    }
+    length
+}
+
+struct ClassName(Option<Site>);
+
+impl core::fmt::Display for ClassName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(site) => write!(f, "({}:{})", site.file(), site.line()),
+            None => write!(f, "(unknown)"),
+        }
+    }
+}
+
+struct SiteName(Option<Site>);
+
+impl core::fmt::Display for SiteName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(site) => write!(f, "{}:{}:{}", site.file(), site.line(), site.column()),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+fn class_name(graph: &Graph, class: usize) -> ClassName {
+    ClassName(graph.classes[class].site)
+}
+
+fn report_header(title: &str) {
+    crate::serial_println!("[LOCKDEP] ======================================================");
+    crate::serial_println!("[LOCKDEP] WARNING: {}", title);
+    crate::serial_println!("[LOCKDEP] ------------------------------------------------------");
+}
+
+fn report_held(cpu: &CpuState, graph: &Graph) {
+    crate::serial_println!("[LOCKDEP] Locks held by CPU {}:", cpu_index());
+    for (index, held) in cpu.held[..cpu.depth].iter().enumerate() {
+        crate::serial_println!(
+            "[LOCKDEP]  #{}: {}{}, at {}",
+            index,
+            class_name(graph, held.class as usize),
+            if held.irq_depth > 0 { " in interrupt" } else { "" },
+            SiteName(held.site)
+        );
+    }
+}
+
+fn report_trace() {
+    crate::serial_println!("[LOCKDEP] Call Trace:");
+    crate::debug::unwind::print_backtrace(&crate::debug::unwind::current_frames(crate::debug::unwind::MAX_FRAMES));
+    crate::serial_println!("[LOCKDEP] Lock validation turned off");
+}
+
+fn report_recursion(cpu: &CpuState, held: &Held, key: &LockClassKey, site: Site) {
+    let graph = GRAPH.lock();
+    report_header("recursive locking detected");
+    crate::serial_println!(
+        "[LOCKDEP] CPU {} is trying to acquire {} at {}",
+        cpu_index(),
+        ClassName(Some(key.site)),
+        SiteName(Some(site))
+    );
+    crate::serial_println!("[LOCKDEP] but already holds the same lock, taken at {}", SiteName(held.site));
+    if held.irq_depth != cpu.irq_depth {
+        crate::serial_println!("[LOCKDEP] The interrupt handler spins on a lock held by the code it interrupted");
+    }
+    report_held(cpu, &graph);
+    report_trace();
+}
+
+fn report_cycle(cpu: &CpuState, graph: &Graph, held: &Held, class: usize, site: Site, length: usize) {
+    let from = held.class as usize;
+    report_header("possible circular locking dependency detected");
+    crate::serial_println!(
+        "[LOCKDEP] CPU {} is trying to acquire {} at {}",
+        cpu_index(),
+        class_name(graph, class),
+        SiteName(Some(site))
+    );
+    crate::serial_println!(
+        "[LOCKDEP] while holding {}, taken at {},",
+        class_name(graph, from),
+        SiteName(held.site)
+    );
+    crate::serial_println!("[LOCKDEP] but the existing dependency chain takes them in the opposite order:");
+
+    for step in 1..length {
+        let (a, b) = (graph.queue[step - 1], graph.queue[step]);
+        let dependency = graph.dependencies[..graph.dependency_count]
+            .iter()
+            .find(|dependency| dependency.from == a && dependency.to == b);
+        crate::serial_println!(
+            "[LOCKDEP]  -> {} taken at {}",
+            class_name(graph, b as usize),
+            SiteName(dependency.and_then(|d| d.taken_at))
+        );
+        crate::serial_println!(
+            "[LOCKDEP]     while holding {}, taken at {}",
+            class_name(graph, a as usize),
+            SiteName(dependency.and_then(|d| d.held_at))
+        );
+    }
+
+    crate::serial_println!("[LOCKDEP] Possible unsafe locking scenario:");
+    crate::serial_println!("[LOCKDEP]   CPU0: lock {}", class_name(graph, from));
+    crate::serial_println!("[LOCKDEP]   CPU1: lock {}", class_name(graph, class));
+    crate::serial_println!("[LOCKDEP]   CPU0: lock {}  <- waits for CPU1", class_name(graph, class));
+    crate::serial_println!("[LOCKDEP]   CPU1: lock {}  <- waits for CPU0", class_name(graph, from));
+    report_held(cpu, graph);
+    report_trace();
+}
+
+fn report_irq_inversion(cpu: &CpuState, graph: &Graph, class: usize) {
+    let info = &graph.classes[class];
+    report_header("inconsistent lock state");
+    crate::serial_println!("[LOCKDEP] {} is taken in interrupt context at {}", class_name(graph, class), SiteName(info.irq_site));
+    crate::serial_println!("[LOCKDEP] and with interrupts enabled at {}", SiteName(info.enabled_site));
+    crate::serial_println!("[LOCKDEP] An interrupt arriving while the lock is held there spins on it forever.");
+    report_held(cpu, graph);
+    report_trace();
+}
+
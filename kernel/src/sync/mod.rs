@@ -1,7 +1,10 @@
 pub mod spinlock;
+pub mod lockdep;
+pub mod mutex;
 pub mod rcu;
 pub mod rwlock;
 
+pub use mutex::{Mutex, MutexGuard};
 pub use spinlock::{SpinLock, SpinLockGuard, RwSpinLock, RwSpinLockReadGuard, RwSpinLockWriteGuard};
 pub use rcu::{rcu_read_lock, rcu_read_unlock, synchronize_rcu, call_rcu, RcuPointer, RcuList};
 pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard, SeqLock, TicketLock, McsLock, McsNode};
\ No newline at end of file
@@ -0,0 +1,128 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::panic::Location;
+
+#[cfg(debug_assertions)]
+use super::lockdep::{self, LockClassKey};
+
+/// spin::Mutex checked by the lock validator in debug builds. The lock
+/// class is the place the mutex was created; release builds compile down to
+/// the plain spin lock.
+pub struct Mutex<T: ?Sized> {
+    #[cfg(debug_assertions)]
+    key: LockClassKey,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(debug_assertions)]
+            key: LockClassKey::new(Location::caller()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    #[inline]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // Validate before spinning, so a deadlock is reported instead of hung
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.address(), &self.key, Location::caller(), false);
+
+        MutexGuard {
+            inner: self.inner.lock(),
+            #[cfg(debug_assertions)]
+            lock: self.address(),
+        }
+    }
+
+    #[track_caller]
+    #[inline]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+
+        #[cfg(debug_assertions)]
+        lockdep::acquire(self.address(), &self.key, Location::caller(), true);
+
+        Some(MutexGuard {
+            inner,
+            #[cfg(debug_assertions)]
+            lock: self.address(),
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.address());
+
+        self.inner.force_unlock();
+    }
+
+    #[cfg(debug_assertions)]
+    fn address(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    inner: spin::MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    lock: usize,
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        lockdep::release(self.lock);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
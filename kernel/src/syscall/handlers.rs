@@ -3,14 +3,32 @@ use alloc::string::String;
 use core::slice;
 use crate::memory::userspace::{validate_user_buffer, USER_SPACE_MANAGER};
 use crate::process::PROCESS_MANAGER;
-use super::{EINVAL, EFAULT, ENOMEM, ENOSYS, EBADF};
+use crate::container::{group, nsproxy, ContainerError};
+use crate::fs::file_ops::{self, FileMode};
+use crate::fs::FileSystemError;
+use crate::memory::mmap::{self, Backing, MapError};
+use crate::process::fork::{self, SpawnError};
+use crate::process::{smp_scheduler, ThreadId};
+use super::{EINVAL, EFAULT, ENOMEM, ENOSYS, ENOEXEC, E2BIG, EBADF, EPERM, ENOENT, ESRCH, EBUSY, ENOSPC, ENAMETOOLONG, EACCES, EEXIST, EIO, EISDIR, ENODEV};
 
 pub fn sys_exit(status: i32) -> Result<usize, usize> {
     crate::serial_println!("Process exiting with status: {}", status);
     
-    let mut pm = PROCESS_MANAGER.lock();
-    if let Some(current) = pm.current_process {
-        pm.terminate_process(current);
+    let current = PROCESS_MANAGER.lock().current_process;
+    if let Some(current) = current {
+        crate::virt::ioctl::release_process(current.0);
+        crate::serial::tty::release_process(current.0);
+        crate::input::evdev::release_process(current.0);
+        crate::nt::wdm::release_process(current.0);
+        crate::net::stream::release_process(current.0);
+        crate::io_uring::release_process(current.0);
+        crate::graphics::client::release_process(current.0);
+        crate::container::release_process(current.0);
+        file_ops::release_process(current.0);
+        mmap::release_process(current.0);
+        fork::release_process(current.0);
+        crate::drivers::block::release_process(current.0);
+        PROCESS_MANAGER.lock().terminate_process(current);
     }
     
     loop {
@@ -41,6 +59,26 @@ pub fn sys_read(fd: usize, buf: usize, count: usize) -> Result<usize, usize> {
             
             Ok(bytes_read)
         }
+        _ if crate::serial::tty::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+            crate::serial::tty::read(fd, buffer)
+        }
+        _ if crate::input::evdev::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+            crate::input::evdev::read(fd, buffer)
+        }
+        _ if crate::nt::wdm::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+            crate::nt::wdm::read(fd, buffer)
+        }
+        _ if crate::net::stream::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+            crate::net::stream::read(fd, buffer)
+        }
+        _ if file_ops::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, count) };
+            file_ops::sys_read(fd as i32, buffer).map_err(file_errno)
+        }
         _ => Err(EBADF),
     }
 }
@@ -66,27 +104,489 @@ pub fn sys_write(fd: usize, buf: usize, count: usize) -> Result<usize, usize> {
             
             Ok(count)
         }
+        _ if crate::serial::tty::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts(buf as *const u8, count) };
+            crate::serial::tty::write(fd, buffer)
+        }
+        _ if crate::nt::wdm::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts(buf as *const u8, count) };
+            crate::nt::wdm::write(fd, buffer)
+        }
+        _ if crate::net::stream::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts(buf as *const u8, count) };
+            crate::net::stream::write(fd, buffer)
+        }
+        _ if file_ops::owns(fd) => {
+            let buffer = unsafe { slice::from_raw_parts(buf as *const u8, count) };
+            file_ops::sys_write(fd as i32, buffer).map_err(file_errno)
+        }
         _ => Err(EBADF),
     }
 }
 
+// open() flags, as on Linux
+const O_ACCMODE: usize = 0x3;
+const O_WRONLY: usize = 0x1;
+const O_RDWR: usize = 0x2;
+const O_CREAT: usize = 0x40;
+const O_APPEND: usize = 0x400;
+const O_CLOEXEC: usize = 0x80000;
+
+pub fn file_errno(error: FileSystemError) -> usize {
+    match error {
+        FileSystemError::NotFound | FileSystemError::FileNotFound => ENOENT,
+        FileSystemError::PermissionDenied => EACCES,
+        FileSystemError::AlreadyExists => EEXIST,
+        FileSystemError::InvalidPath => EISDIR,
+        FileSystemError::IoError(_) => EIO,
+        FileSystemError::NotSupported => ENOSYS,
+        FileSystemError::Locked => super::EAGAIN,
+    }
+}
+
 pub fn sys_open(path: usize, flags: usize) -> Result<usize, usize> {
-    Err(ENOSYS)
+    let path = super::read_user_path(path)?;
+    if path == crate::virt::ioctl::DEVICE_PATH {
+        return crate::virt::ioctl::open();
+    }
+    if let Some(result) = crate::serial::tty::open(&path) {
+        return result;
+    }
+    if let Some(result) = crate::input::evdev::open(&path) {
+        return result;
+    }
+    if let Some(result) = crate::nt::wdm::open(&path) {
+        return result;
+    }
+    
+    let mut mode = match flags & O_ACCMODE {
+        O_WRONLY => FileMode::WRITE,
+        O_RDWR => FileMode::READ | FileMode::WRITE,
+        _ => FileMode::READ,
+    };
+    if flags & O_CREAT != 0 {
+        mode |= FileMode::CREATE;
+    }
+    if flags & O_APPEND != 0 {
+        mode |= FileMode::APPEND;
+    }
+    if flags & O_CLOEXEC != 0 {
+        mode |= FileMode::CLOEXEC;
+    }
+    file_ops::sys_open(&path, mode.bits()).map(|fd| fd as usize).map_err(file_errno)
 }
 
 pub fn sys_close(fd: usize) -> Result<usize, usize> {
     match fd {
         0 | 1 | 2 => Ok(0),
+        _ if crate::virt::ioctl::close(fd) => Ok(0),
+        _ if crate::serial::tty::close(fd) => Ok(0),
+        _ if crate::input::evdev::close(fd) => Ok(0),
+        _ if crate::nt::wdm::close(fd) => Ok(0),
+        _ if crate::net::stream::close(fd) => Ok(0),
+        _ if crate::io_uring::close(fd) => Ok(0),
+        _ if crate::graphics::client::close(fd) => Ok(0),
+        _ if file_ops::owns(fd) => file_ops::sys_close(fd as i32).map(|()| 0).map_err(file_errno),
         _ => Err(EBADF),
     }
 }
 
+pub fn sys_ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, usize> {
+    if crate::serial::tty::owns(fd) {
+        return crate::serial::tty::ioctl(fd, request, arg);
+    }
+    if crate::input::evdev::owns(fd) {
+        return crate::input::evdev::ioctl(fd, request, arg);
+    }
+    if crate::nt::wdm::owns(fd) {
+        return crate::nt::wdm::ioctl(fd, request, arg);
+    }
+    crate::virt::ioctl::ioctl(fd, request, arg)
+}
+
+fn container_errno(error: ContainerError) -> usize {
+    match error {
+        ContainerError::PermissionDenied => EPERM,
+        ContainerError::NotFound => ENOENT,
+        ContainerError::InvalidState => EBUSY,
+        ContainerError::ResourceLimitExceeded => ENOSPC,
+        _ => EINVAL,
+    }
+}
+
+// Namespace and group changes reach beyond the caller, so they are for
+// administrators only
+fn admin_pid() -> Result<u32, usize> {
+    if !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    PROCESS_MANAGER.lock().current_process.map(|pid| pid.0).ok_or(EINVAL)
+}
+
+pub fn sys_unshare(flags: usize) -> Result<usize, usize> {
+    let pid = admin_pid()?;
+    nsproxy::unshare(pid, flags).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_setns(kind: usize, id: usize) -> Result<usize, usize> {
+    let pid = admin_pid()?;
+    nsproxy::setns(pid, kind, id as u32).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_sethostname(name: usize, len: usize) -> Result<usize, usize> {
+    let pid = PROCESS_MANAGER.lock().current_process.map(|pid| pid.0).ok_or(EINVAL)?;
+    // A process in a UTS namespace of its own may name it
+    if nsproxy::namespaces(pid).uts == 0 && !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    if len > nsproxy::MAX_HOSTNAME_LEN {
+        return Err(EINVAL);
+    }
+    let addr = VirtAddr::try_new(name as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, len) {
+        return Err(EFAULT);
+    }
+    let bytes = unsafe { slice::from_raw_parts(name as *const u8, len) };
+    let hostname = core::str::from_utf8(bytes).map_err(|_| EINVAL)?;
+    nsproxy::set_hostname(pid, hostname).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_gethostname(buf: usize, len: usize) -> Result<usize, usize> {
+    let pid = PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0);
+    let hostname = nsproxy::hostname(pid);
+    if len <= hostname.len() {
+        return Err(ENAMETOOLONG);
+    }
+    let addr = VirtAddr::try_new(buf as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, hostname.len() + 1) {
+        return Err(EFAULT);
+    }
+    let buffer = unsafe { slice::from_raw_parts_mut(buf as *mut u8, hostname.len() + 1) };
+    buffer[..hostname.len()].copy_from_slice(hostname.as_bytes());
+    buffer[hostname.len()] = 0;
+    Ok(0)
+}
+
+/// Bind `source` over `target` in the caller's mount namespace
+pub fn sys_mount(source: usize, target: usize) -> Result<usize, usize> {
+    let pid = admin_pid()?;
+    let source = super::read_user_path(source)?;
+    let target = super::read_user_path(target)?;
+    nsproxy::bind_mount(pid, &source, &target).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_group_create(parent: usize, name: usize) -> Result<usize, usize> {
+    admin_pid()?;
+    let name = super::read_user_path(name)?;
+    group::create(parent as u32, &name).map(|id| id as usize).map_err(container_errno)
+}
+
+pub fn sys_group_set(id: usize, resource: usize, value: usize) -> Result<usize, usize> {
+    admin_pid()?;
+    let resource = group::Resource::from_raw(resource).ok_or(EINVAL)?;
+    group::set(id as u32, resource, value as u64).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_group_join(id: usize) -> Result<usize, usize> {
+    let pid = admin_pid()?;
+    group::join(pid, id as u32).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_group_destroy(id: usize) -> Result<usize, usize> {
+    admin_pid()?;
+    group::destroy(id as u32).map_err(container_errno)?;
+    Ok(0)
+}
+
+pub fn sys_syslog(action: usize, buf: usize, len: usize) -> Result<usize, usize> {
+    crate::klog::syslog(action, buf, len)
+}
+
+fn spawn_errno(error: SpawnError) -> usize {
+    match error {
+        SpawnError::NoSuchProcess => ESRCH,
+        SpawnError::NoMemory => ENOMEM,
+        SpawnError::NotFound => ENOENT,
+        SpawnError::AccessDenied => EACCES,
+        SpawnError::BadImage => ENOEXEC,
+    }
+}
+
+// Limit on exec and spawn arguments
+const MAX_ARGS: usize = 256;
+
+// The command line from a NULL-terminated argv array, or just the path
+// when there is none
+fn read_command_line(path: &str, argv: usize) -> Result<String, usize> {
+    if argv == 0 {
+        return Ok(String::from(path));
+    }
+    let mut args = alloc::vec::Vec::new();
+    loop {
+        let slot = argv + args.len() * core::mem::size_of::<usize>();
+        let addr = VirtAddr::try_new(slot as u64).map_err(|_| EFAULT)?;
+        if !validate_user_buffer(addr, core::mem::size_of::<usize>()) {
+            return Err(EFAULT);
+        }
+        let arg = unsafe { *(slot as *const usize) };
+        if arg == 0 {
+            break;
+        }
+        if args.len() == MAX_ARGS {
+            return Err(E2BIG);
+        }
+        args.push(super::read_user_path(arg)?);
+    }
+    Ok(args.join(" "))
+}
+
+fn executor_pid() -> Result<u32, usize> {
+    crate::process::executor::EXECUTOR.lock().get_current_pid().ok_or(ESRCH)
+}
+
 pub fn sys_fork() -> Result<usize, usize> {
-    Err(ENOSYS)
+    fork::fork(executor_pid()?).map(|pid| pid as usize).map_err(spawn_errno)
+}
+
+pub fn sys_vfork() -> Result<usize, usize> {
+    fork::vfork(executor_pid()?).map(|pid| pid as usize).map_err(spawn_errno)
 }
 
 pub fn sys_exec(path: usize, argv: usize) -> Result<usize, usize> {
-    Err(ENOSYS)
+    let path = super::read_user_path(path)?;
+    let command_line = read_command_line(&path, argv)?;
+    fork::exec(executor_pid()?, &path, command_line).map_err(spawn_errno)?;
+    Ok(0)
+}
+
+pub fn sys_spawn(path: usize, argv: usize) -> Result<usize, usize> {
+    let path = super::read_user_path(path)?;
+    let command_line = read_command_line(&path, argv)?;
+    fork::spawn(Some(executor_pid()?), &path, command_line, true)
+        .map(|pid| pid as usize)
+        .map_err(spawn_errno)
+}
+
+/// Start a thread in the caller's process at `entry`, with `arg` in rdi and
+/// `stack` as its stack pointer. Returns the thread's id.
+pub fn sys_thread_create(entry: usize, stack: usize, arg: usize) -> Result<usize, usize> {
+    let entry = VirtAddr::try_new(entry as u64).map_err(|_| EFAULT)?;
+    let stack = VirtAddr::try_new(stack as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(entry, 1) || !validate_user_buffer(stack, 0) {
+        return Err(EFAULT);
+    }
+    fork::thread(executor_pid()?, entry, stack, arg as u64)
+        .map(|tid| tid as usize)
+        .map_err(spawn_errno)
+}
+
+/// End the calling thread with `status`. From the process's first thread,
+/// this is exit().
+pub fn sys_thread_exit(status: usize) -> Result<usize, usize> {
+    if !fork::exit_thread(executor_pid()?, status as i32) {
+        return sys_exit(status as i32);
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Wait for thread `tid` of the caller's process to exit, and return its
+/// status. A thread can be joined once.
+pub fn sys_thread_join(tid: usize) -> Result<usize, usize> {
+    let caller = executor_pid()?;
+    if tid as u32 == caller {
+        return Err(EINVAL);
+    }
+    // Blocked until the thread's exit wakes it, as sys_sleep is until its
+    // timer does
+    loop {
+        if let Some(status) = fork::join(caller, tid as u32).map_err(spawn_errno)? {
+            return Ok(status as u32 as usize);
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+// The thread `tid` names, 0 for the caller, if the caller may change it:
+// its own process's threads, or anyone's for an administrator
+fn affinity_target(tid: usize) -> Result<ThreadId, usize> {
+    use crate::process::thread::THREAD_MANAGER;
+
+    let threads = THREAD_MANAGER.lock();
+    let tid = match tid {
+        0 => threads.get_current_thread().ok_or(ESRCH)?,
+        tid => ThreadId(tid as u32),
+    };
+    let owner = threads.get_thread(tid).ok_or(ESRCH)?.process_id;
+    drop(threads);
+    if PROCESS_MANAGER.lock().current_process != Some(owner) && !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    Ok(tid)
+}
+
+pub fn sys_sched_setaffinity(tid: usize, mask: usize) -> Result<usize, usize> {
+    let tid = affinity_target(tid)?;
+    if !smp_scheduler::set_thread_cpu_affinity(tid, mask as u64) {
+        return Err(EINVAL);
+    }
+    Ok(0)
+}
+
+pub fn sys_sched_getaffinity(tid: usize) -> Result<usize, usize> {
+    Ok(smp_scheduler::get_thread_cpu_affinity(affinity_target(tid)?) as usize)
+}
+
+pub fn sys_cpu_set_online(cpu: usize, online: usize) -> Result<usize, usize> {
+    use crate::smp::hotplug::{self, HotplugError};
+
+    if !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    let result = if online != 0 {
+        hotplug::cpu_up(cpu as u32)
+    } else {
+        hotplug::cpu_down(cpu as u32).map(|_| ())
+    };
+    match result {
+        // Already where the caller wants it
+        Ok(()) | Err(HotplugError::AlreadyOnline) | Err(HotplugError::AlreadyOffline) => Ok(0),
+        Err(HotplugError::NoSuchCpu) => Err(ENODEV),
+        Err(HotplugError::BootCpu) => Err(EBUSY),
+        Err(HotplugError::StartFailed) => Err(EIO),
+    }
+}
+
+/// User, kernel, interrupt and idle nanoseconds, as sys_cpu_times writes
+/// them
+#[repr(C)]
+struct CpuTimesInfo {
+    user_ns: u64,
+    kernel_ns: u64,
+    irq_ns: u64,
+    idle_ns: u64,
+}
+
+pub fn sys_cpu_times(cpu: usize, info_ptr: usize) -> Result<usize, usize> {
+    if crate::smp::SMP_MANAGER.lock().get_cpu(cpu as u32).is_none() {
+        return Err(ENODEV);
+    }
+    let addr = VirtAddr::try_new(info_ptr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(addr, core::mem::size_of::<CpuTimesInfo>()) {
+        return Err(EFAULT);
+    }
+    let times = crate::smp::cpustat::times(cpu as u32);
+    let info = CpuTimesInfo {
+        user_ns: times.user_ns,
+        kernel_ns: times.kernel_ns,
+        irq_ns: times.irq_ns,
+        idle_ns: times.idle_ns,
+    };
+    unsafe { core::ptr::write_unaligned(info_ptr as *mut CpuTimesInfo, info) };
+    Ok(0)
+}
+
+// Process `pid`, 0 for the caller
+fn ioprio_target(pid: usize) -> Result<u32, usize> {
+    let caller = executor_pid()?;
+    match pid {
+        0 => Ok(caller),
+        pid if crate::process::executor::EXECUTOR.lock().get_process(pid as u32).is_some() => Ok(pid as u32),
+        _ => Err(ESRCH),
+    }
+}
+
+/// Set a process's I/O priority, in Linux's encoding. Other processes'
+/// priorities and the realtime class are an administrator's to set.
+pub fn sys_ioprio_set(pid: usize, ioprio: usize) -> Result<usize, usize> {
+    use crate::drivers::block::{self, IoClass, IoPriority};
+
+    let priority = u16::try_from(ioprio).ok().and_then(IoPriority::from_raw).ok_or(EINVAL)?;
+    let pid = ioprio_target(pid)?;
+    let admin = crate::security::accounts::caller_is_admin();
+    if (pid != executor_pid()? || priority.class == IoClass::RealTime) && !admin {
+        return Err(EPERM);
+    }
+    block::set_priority(pid, priority);
+    Ok(0)
+}
+
+pub fn sys_ioprio_get(pid: usize) -> Result<usize, usize> {
+    Ok(crate::drivers::block::priority(ioprio_target(pid)?).to_raw() as usize)
+}
+
+/// Listen for TCP connections on `port`
+pub fn sys_listen(port: usize) -> Result<usize, usize> {
+    crate::net::stream::listen(u16::try_from(port).map_err(|_| EINVAL)?)
+}
+
+/// Connect to `port` at `address`, an IPv4 address as a number
+pub fn sys_connect(address: usize, port: usize) -> Result<usize, usize> {
+    let address = u32::try_from(address).map_err(|_| EINVAL)?;
+    crate::net::stream::connect(address, u16::try_from(port).map_err(|_| EINVAL)?)
+}
+
+pub fn sys_accept(fd: usize) -> Result<usize, usize> {
+    crate::net::stream::accept(fd)
+}
+
+pub fn sys_io_uring_setup(entries: usize, params: usize) -> Result<usize, usize> {
+    crate::io_uring::setup(entries, params)
+}
+
+pub fn sys_io_uring_enter(
+    fd: usize,
+    to_submit: usize,
+    min_complete: usize,
+    flags: usize,
+    timeout_ms: usize,
+) -> Result<usize, usize> {
+    crate::io_uring::enter(fd, to_submit, min_complete, flags, timeout_ms)
+}
+
+/// The caller's control-flow enforcement: `op` 0 gets its features, 1
+/// gives up those in `features`, 2 locks them. Returns the features left.
+pub fn sys_cet_control(op: usize, features: usize) -> Result<usize, usize> {
+    use crate::security::mitigations::{self, CET_GET};
+
+    let mut executor = crate::process::executor::EXECUTOR.lock();
+    let pid = executor.get_current_pid().ok_or(ESRCH)?;
+    let pcb = executor.get_process_mut(pid).ok_or(ESRCH)?;
+    if op != CET_GET && pcb.cet.locked {
+        return Err(EPERM);
+    }
+    let features = mitigations::cet_control(&mut pcb.cet, op, features).map_err(|_| EINVAL)?;
+    Ok(features.bits() as usize)
+}
+
+/// Power the system off once it has been taken down in order; for
+/// administrators only
+pub fn sys_shutdown() -> Result<usize, usize> {
+    shutdown(crate::power::shutdown::ShutdownAction::PowerOff)
+}
+
+/// Reset the system the same way
+pub fn sys_reboot() -> Result<usize, usize> {
+    shutdown(crate::power::shutdown::ShutdownAction::Reboot)
+}
+
+// The sequence runs from the main loop, as the caller is among the
+// processes it ends
+fn shutdown(action: crate::power::shutdown::ShutdownAction) -> Result<usize, usize> {
+    if !crate::security::accounts::caller_is_admin() {
+        return Err(EPERM);
+    }
+    if !crate::power::shutdown::request(action) {
+        return Err(EBUSY);
+    }
+    Ok(0)
 }
 
 pub fn sys_wait(pid: usize) -> Result<usize, usize> {
@@ -94,108 +594,118 @@ pub fn sys_wait(pid: usize) -> Result<usize, usize> {
 }
 
 pub fn sys_kill(pid: usize, signal: usize) -> Result<usize, usize> {
-    let pid = crate::process::ProcessId(pid as u32);
+    // The pid is numbered in the caller's PID namespace
+    let caller = PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0);
+    let pid = nsproxy::resolve_pid(caller, pid as u32).ok_or(ESRCH)?;
+    let pid = crate::process::ProcessId(pid);
+    crate::virt::ioctl::release_process(pid.0);
+    crate::serial::tty::release_process(pid.0);
+    crate::input::evdev::release_process(pid.0);
+    crate::nt::wdm::release_process(pid.0);
+    crate::net::stream::release_process(pid.0);
+    crate::io_uring::release_process(pid.0);
+    crate::graphics::client::release_process(pid.0);
+    crate::container::release_process(pid.0);
+    file_ops::release_process(pid.0);
+    mmap::release_process(pid.0);
+    fork::release_process(pid.0);
+    crate::drivers::block::release_process(pid.0);
     let mut pm = PROCESS_MANAGER.lock();
     
     if pm.get_process(pid).is_some() {
         pm.terminate_process(pid);
         Ok(0)
     } else {
-        Err(3)
+        Err(ESRCH)
     }
 }
 
 pub fn sys_getpid() -> Result<usize, usize> {
     let pm = PROCESS_MANAGER.lock();
     match pm.current_process {
-        Some(pid) => Ok(pid.0 as usize),
+        Some(pid) => Ok(nsproxy::getpid(pid.0) as usize),
         None => Ok(0),
     }
 }
 
 pub fn sys_brk(addr: usize) -> Result<usize, usize> {
     let new_brk = VirtAddr::new(addr as u64);
-    let mut usm = USER_SPACE_MANAGER.lock();
+    let pid = current_pid()?;
     
-    let pm = PROCESS_MANAGER.lock();
-    if let Some(current_pid) = pm.current_process {
-        if let Some(space) = usm.get_address_space_mut(current_pid.0 as u64) {
-            match space.set_brk(new_brk) {
-                Ok(old_brk) => Ok(old_brk.as_u64() as usize),
-                Err(_) => Err(ENOMEM),
-            }
-        } else {
-            Err(EINVAL)
+    let mut usm = USER_SPACE_MANAGER.lock();
+    if let Some(space) = usm.get_address_space_mut(pid as u64) {
+        match space.set_brk(new_brk) {
+            Ok(old_brk) => Ok(old_brk.as_u64() as usize),
+            Err(_) => Err(ENOMEM),
         }
     } else {
         Err(EINVAL)
     }
 }
 
+fn map_errno(error: MapError) -> usize {
+    match error {
+        MapError::InvalidArgument => EINVAL,
+        MapError::NoMemory | MapError::NotMapped => ENOMEM,
+        MapError::Io => EIO,
+    }
+}
+
+fn current_pid() -> Result<u32, usize> {
+    PROCESS_MANAGER.lock().current_process.map(|pid| pid.0).ok_or(EINVAL)
+}
+
 pub fn sys_mmap(addr: usize, length: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> Result<usize, usize> {
-    use crate::memory::userspace::MemoryRegionType;
+    let pid = current_pid()?;
     
-    let mut usm = USER_SPACE_MANAGER.lock();
-    let pm = PROCESS_MANAGER.lock();
-    
-    if let Some(current_pid) = pm.current_process {
-        if let Some(space) = usm.get_address_space_mut(current_pid.0 as u64) {
-            let alignment = 0x1000;
-            
-            let region_addr = if addr == 0 {
-                space.find_free_region(length as u64, alignment)
-                    .ok_or(ENOMEM)?
-            } else {
-                VirtAddr::new(addr as u64)
-            };
-            
-            let region = crate::memory::userspace::MemoryRegion::new(
-                region_addr,
-                region_addr + length,
-                MemoryRegionType::Data,
-            );
-            
-            space.add_region(region)
-                .map_err(|_| ENOMEM)?;
-            
-            Ok(region_addr.as_u64() as usize)
-        } else {
-            Err(EINVAL)
+    let backing = if flags & mmap::MAP_ANONYMOUS != 0 {
+        Backing::Anonymous
+    } else if let Some(key) = crate::io_uring::mappable(fd).or_else(|| crate::graphics::client::mappable(fd)) {
+        // A private copy of a ring would never see the kernel's writes,
+        // nor would the kernel see what is drawn in a window's
+        if flags & mmap::MAP_SHARED == 0 {
+            return Err(EINVAL);
         }
+        Backing::Object(key, offset as u64)
     } else {
-        Err(EINVAL)
-    }
+        // A shared writable mapping writes to the file, so needs it open for writing
+        let (key, readable, writable) = crate::fs::file_ops::mappable(fd).ok_or(EBADF)?;
+        if !readable || (flags & mmap::MAP_SHARED != 0 && prot & mmap::PROT_WRITE != 0 && !writable) {
+            return Err(EACCES);
+        }
+        Backing::Object(key, offset as u64)
+    };
+    
+    mmap::map(pid, VirtAddr::new(addr as u64), length as u64, prot, flags, backing)
+        .map(|start| start.as_u64() as usize)
+        .map_err(map_errno)
 }
 
 pub fn sys_munmap(addr: usize, length: usize) -> Result<usize, usize> {
-    let mut usm = USER_SPACE_MANAGER.lock();
-    let pm = PROCESS_MANAGER.lock();
-    
-    if let Some(current_pid) = pm.current_process {
-        if let Some(space) = usm.get_address_space_mut(current_pid.0 as u64) {
-            let vaddr = VirtAddr::new(addr as u64);
-            
-            if space.remove_region(vaddr).is_some() {
-                Ok(0)
-            } else {
-                Err(EINVAL)
-            }
-        } else {
-            Err(EINVAL)
-        }
-    } else {
-        Err(EINVAL)
-    }
+    let pid = current_pid()?;
+    mmap::unmap(pid, VirtAddr::new(addr as u64), length as u64).map_err(map_errno)?;
+    Ok(0)
+}
+
+pub fn sys_msync(addr: usize, length: usize, flags: usize) -> Result<usize, usize> {
+    let pid = current_pid()?;
+    mmap::sync(pid, VirtAddr::new(addr as u64), length as u64, flags).map_err(map_errno)?;
+    Ok(0)
 }
 
 pub fn sys_sleep(milliseconds: usize) -> Result<usize, usize> {
-    let start = crate::cpu::rdtsc();
-    let cpu_freq = 2_000_000_000;
-    let cycles_to_wait = (milliseconds as u64 * cpu_freq) / 1000;
+    use crate::process::executor::EXECUTOR;
     
-    while crate::cpu::rdtsc() - start < cycles_to_wait {
-        x86_64::instructions::hlt();
+    let deadline = crate::time::clocksource::now_ns().saturating_add(milliseconds as u64 * 1_000_000);
+    
+    // Off the ready queue until an hrtimer wakes it
+    {
+        let mut executor = EXECUTOR.lock();
+        if let Some(pid) = executor.get_current_pid() {
+            executor.sleep_until(pid, deadline);
+        }
     }
+    crate::time::hrtimer::sleep_until(deadline);
     
     Ok(0)
 }
@@ -206,44 +716,35 @@ pub fn sys_gettime() -> Result<usize, usize> {
     Ok(seconds_since_boot as usize)
 }
 
-pub fn sys_create_window(x: usize, y: usize, width: usize, height: usize) -> Result<usize, usize> {
-    use crate::graphics::window::{Window, WINDOW_MANAGER};
-    
-    let mut wm = WINDOW_MANAGER.lock();
-    if let Some(ref mut manager) = *wm {
-        let window = Window::new(
-            x as i32,
-            y as i32,
-            width as u32,
-            height as u32,
-            String::from("User Window"),
-        );
-        
-        let id = manager.create_window(window);
-        Ok(id.0 as usize)
+/// Open a window whose client area is `width` by `height`, titled with the
+/// string at `title` unless it is null; see `graphics::client`
+pub fn sys_create_window(x: usize, y: usize, width: usize, height: usize, title: usize) -> Result<usize, usize> {
+    let title = if title == 0 {
+        None
     } else {
-        Err(ENOSYS)
-    }
+        let title = super::read_user_path(title)?;
+        if title.len() > crate::graphics::client::MAX_TITLE {
+            return Err(ENAMETOOLONG);
+        }
+        Some(title)
+    };
+    crate::graphics::client::create(x, y, width, height, title)
 }
 
-pub fn sys_destroy_window(window_id: usize) -> Result<usize, usize> {
-    use crate::graphics::window::{WindowId, WINDOW_MANAGER};
-    
-    let mut wm = WINDOW_MANAGER.lock();
-    if let Some(ref mut manager) = *wm {
-        manager.destroy_window(WindowId(window_id as u32));
+pub fn sys_destroy_window(fd: usize) -> Result<usize, usize> {
+    if crate::graphics::client::close(fd) {
         Ok(0)
     } else {
-        Err(ENOSYS)
+        Err(EBADF)
     }
 }
 
-pub fn sys_draw_window(window_id: usize, buffer_ptr: usize) -> Result<usize, usize> {
-    Err(ENOSYS)
+pub fn sys_draw_window(fd: usize, damage: usize) -> Result<usize, usize> {
+    crate::graphics::client::draw(fd, damage)
 }
 
-pub fn sys_handle_event(event_ptr: usize) -> Result<usize, usize> {
-    Err(ENOSYS)
+pub fn sys_handle_event(fd: usize, event: usize) -> Result<usize, usize> {
+    crate::graphics::client::next_event(fd, event)
 }
 
 pub fn sys_get_screen_info(info_ptr: usize) -> Result<usize, usize> {
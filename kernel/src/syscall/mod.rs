@@ -24,6 +24,37 @@ pub enum SyscallNumber {
     Munmap = 12,
     Sleep = 13,
     GetTime = 14,
+    Ioctl = 15,
+    Unshare = 16,
+    SetNs = 17,
+    SetHostname = 18,
+    GetHostname = 19,
+    Mount = 20,
+    GroupCreate = 21,
+    GroupSet = 22,
+    GroupJoin = 23,
+    GroupDestroy = 24,
+    Syslog = 25,
+    Msync = 26,
+    Vfork = 27,
+    Spawn = 28,
+    SchedSetAffinity = 29,
+    SchedGetAffinity = 30,
+    CpuSetOnline = 31,
+    CpuTimes = 32,
+    IoprioSet = 33,
+    IoprioGet = 34,
+    Listen = 35,
+    Connect = 36,
+    Accept = 37,
+    IoUringSetup = 38,
+    IoUringEnter = 39,
+    CetControl = 40,
+    Shutdown = 41,
+    Reboot = 42,
+    ThreadCreate = 43,
+    ThreadExit = 44,
+    ThreadJoin = 45,
     CreateWindow = 100,
     DestroyWindow = 101,
     DrawWindow = 102,
@@ -31,6 +62,122 @@ pub enum SyscallNumber {
     GetScreenInfo = 104,
 }
 
+impl SyscallNumber {
+    pub const ALL: [SyscallNumber; 51] = [
+        SyscallNumber::Exit,
+        SyscallNumber::Read,
+        SyscallNumber::Write,
+        SyscallNumber::Open,
+        SyscallNumber::Close,
+        SyscallNumber::Fork,
+        SyscallNumber::Exec,
+        SyscallNumber::Wait,
+        SyscallNumber::Kill,
+        SyscallNumber::GetPid,
+        SyscallNumber::Brk,
+        SyscallNumber::Mmap,
+        SyscallNumber::Munmap,
+        SyscallNumber::Sleep,
+        SyscallNumber::GetTime,
+        SyscallNumber::Ioctl,
+        SyscallNumber::Unshare,
+        SyscallNumber::SetNs,
+        SyscallNumber::SetHostname,
+        SyscallNumber::GetHostname,
+        SyscallNumber::Mount,
+        SyscallNumber::GroupCreate,
+        SyscallNumber::GroupSet,
+        SyscallNumber::GroupJoin,
+        SyscallNumber::GroupDestroy,
+        SyscallNumber::Syslog,
+        SyscallNumber::Msync,
+        SyscallNumber::Vfork,
+        SyscallNumber::Spawn,
+        SyscallNumber::SchedSetAffinity,
+        SyscallNumber::SchedGetAffinity,
+        SyscallNumber::CpuSetOnline,
+        SyscallNumber::CpuTimes,
+        SyscallNumber::IoprioSet,
+        SyscallNumber::IoprioGet,
+        SyscallNumber::Listen,
+        SyscallNumber::Connect,
+        SyscallNumber::Accept,
+        SyscallNumber::IoUringSetup,
+        SyscallNumber::IoUringEnter,
+        SyscallNumber::CetControl,
+        SyscallNumber::Shutdown,
+        SyscallNumber::Reboot,
+        SyscallNumber::ThreadCreate,
+        SyscallNumber::ThreadExit,
+        SyscallNumber::ThreadJoin,
+        SyscallNumber::CreateWindow,
+        SyscallNumber::DestroyWindow,
+        SyscallNumber::DrawWindow,
+        SyscallNumber::HandleEvent,
+        SyscallNumber::GetScreenInfo,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SyscallNumber::Exit => "exit",
+            SyscallNumber::Read => "read",
+            SyscallNumber::Write => "write",
+            SyscallNumber::Open => "open",
+            SyscallNumber::Close => "close",
+            SyscallNumber::Fork => "fork",
+            SyscallNumber::Exec => "exec",
+            SyscallNumber::Wait => "wait",
+            SyscallNumber::Kill => "kill",
+            SyscallNumber::GetPid => "getpid",
+            SyscallNumber::Brk => "brk",
+            SyscallNumber::Mmap => "mmap",
+            SyscallNumber::Munmap => "munmap",
+            SyscallNumber::Sleep => "sleep",
+            SyscallNumber::GetTime => "gettime",
+            SyscallNumber::Ioctl => "ioctl",
+            SyscallNumber::Unshare => "unshare",
+            SyscallNumber::SetNs => "setns",
+            SyscallNumber::SetHostname => "sethostname",
+            SyscallNumber::GetHostname => "gethostname",
+            SyscallNumber::Mount => "mount",
+            SyscallNumber::GroupCreate => "group_create",
+            SyscallNumber::GroupSet => "group_set",
+            SyscallNumber::GroupJoin => "group_join",
+            SyscallNumber::GroupDestroy => "group_destroy",
+            SyscallNumber::Syslog => "syslog",
+            SyscallNumber::Msync => "msync",
+            SyscallNumber::Vfork => "vfork",
+            SyscallNumber::Spawn => "spawn",
+            SyscallNumber::SchedSetAffinity => "sched_setaffinity",
+            SyscallNumber::SchedGetAffinity => "sched_getaffinity",
+            SyscallNumber::CpuSetOnline => "cpu_set_online",
+            SyscallNumber::CpuTimes => "cpu_times",
+            SyscallNumber::IoprioSet => "ioprio_set",
+            SyscallNumber::IoprioGet => "ioprio_get",
+            SyscallNumber::Listen => "listen",
+            SyscallNumber::Connect => "connect",
+            SyscallNumber::Accept => "accept",
+            SyscallNumber::IoUringSetup => "io_uring_setup",
+            SyscallNumber::IoUringEnter => "io_uring_enter",
+            SyscallNumber::CetControl => "cet_control",
+            SyscallNumber::Shutdown => "shutdown",
+            SyscallNumber::Reboot => "reboot",
+            SyscallNumber::ThreadCreate => "thread_create",
+            SyscallNumber::ThreadExit => "thread_exit",
+            SyscallNumber::ThreadJoin => "thread_join",
+            SyscallNumber::CreateWindow => "create_window",
+            SyscallNumber::DestroyWindow => "destroy_window",
+            SyscallNumber::DrawWindow => "draw_window",
+            SyscallNumber::HandleEvent => "handle_event",
+            SyscallNumber::GetScreenInfo => "get_screen_info",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|syscall| syscall.name().eq_ignore_ascii_case(name))
+    }
+}
+
 #[derive(Debug)]
 pub struct SyscallContext {
     pub number: usize,
@@ -96,6 +243,9 @@ extern "C" fn syscall_handler() {
     let _result = handle_syscall(number, arg1, arg2, arg3, arg4, arg5, arg6);
 }
 
+crate::declare_trace_event!(pub TRACE_SYS_ENTER, "syscalls", "sys_enter", ["nr", "arg1", "arg2", "arg3", "arg4", "arg5", "arg6"]);
+crate::declare_trace_event!(pub TRACE_SYS_EXIT, "syscalls", "sys_exit", ["nr", "ret"]);
+
 #[no_mangle]
 pub extern "C" fn handle_syscall(
     number: usize,
@@ -108,18 +258,133 @@ pub extern "C" fn handle_syscall(
 ) -> isize {
     // Track syscall performance
     let probe = crate::perf::SyscallProbe::start(number);
+    TRACE_SYS_ENTER.emit(&[number as u64, arg1 as u64, arg2 as u64, arg3 as u64, arg4 as u64, arg5 as u64, arg6 as u64]);
     
     let context = SyscallContext::from_registers(number, arg1, arg2, arg3, arg4, arg5, arg6);
     
-    let result = match dispatch_syscall(context) {
+    let result = match sandbox_filter(&context)
+        .and_then(|()| charge_memory(&context))
+        .and_then(|()| dispatch_syscall(context))
+    {
         Ok(result) => result as isize,
         Err(errno) => -(errno as isize),
     };
     
     probe.end();
+    TRACE_SYS_EXIT.emit(&[number as u64, result as u64]);
     result
 }
 
+// Exit status of a process killed for breaking its sandbox (128 + SIGSYS)
+const SANDBOX_KILL_STATUS: i32 = 159;
+
+// O_ACCMODE bits of open()'s flags
+const OPEN_ACCESS_MODE: usize = 0x3;
+
+// Apply the caller's sandbox before the handler runs: the syscall filter, then
+// path and memory checks for the calls that take them
+fn sandbox_filter(context: &SyscallContext) -> Result<(), usize> {
+    use crate::security::sandbox::{self, ResourceType, SandboxError, ViolationAction};
+
+    let pid = match crate::process::PROCESS_MANAGER.lock().current_process {
+        Some(pid) => pid.0 as u64,
+        None => return Ok(()),
+    };
+    let args = [
+        context.arg1 as u64,
+        context.arg2 as u64,
+        context.arg3 as u64,
+        context.arg4 as u64,
+        context.arg5 as u64,
+        context.arg6 as u64,
+    ];
+
+    let result = sandbox::check_syscall(pid, context.number as u32, &args).and_then(|()| {
+        match context.number {
+            3 => {
+                let path = read_user_path(context.arg1).map_err(|_| SandboxError::PathDenied)?;
+                sandbox::check_path_access(pid, &path, context.arg2 & OPEN_ACCESS_MODE != 0)
+            }
+            6 | 28 => {
+                let path = read_user_path(context.arg1).map_err(|_| SandboxError::PathDenied)?;
+                sandbox::check_path_access(pid, &path, false)
+            }
+            10 | 11 => {
+                let usage = memory_after(pid, context);
+                sandbox::check_resource_limit(pid, ResourceType::Memory, usage)
+            }
+            _ => Ok(()),
+        }
+    });
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            if sandbox::violation_action(pid) == ViolationAction::Kill {
+                handlers::sys_exit(SANDBOX_KILL_STATUS)?;
+            }
+            Err(match error {
+                SandboxError::PathDenied => EACCES,
+                SandboxError::ResourceExceeded => ENOMEM,
+                _ => EPERM,
+            })
+        }
+    }
+}
+
+// Charge brk and mmap growth to the caller's resource group
+fn charge_memory(context: &SyscallContext) -> Result<(), usize> {
+    if !matches!(context.number, 10 | 11) {
+        return Ok(());
+    }
+    let pid = match crate::process::PROCESS_MANAGER.lock().current_process {
+        Some(pid) => pid.0,
+        None => return Ok(()),
+    };
+    let usage = memory_after(pid as u64, context);
+    crate::container::group::charge_memory(pid, usage).map_err(|_| ENOMEM)
+}
+
+// Bytes of user memory the process would hold after a brk or mmap
+fn memory_after(pid: u64, context: &SyscallContext) -> u64 {
+    use crate::memory::userspace::USER_SPACE_MANAGER;
+
+    let usm = USER_SPACE_MANAGER.lock();
+    let space = match usm.get_address_space(pid) {
+        Some(space) => space,
+        None => return 0,
+    };
+    let mapped: u64 = space.regions.values().map(|region| region.size()).sum();
+    let heap_start = space.heap_start.as_u64();
+    let heap = space.brk.as_u64().saturating_sub(heap_start);
+
+    match context.number {
+        10 => mapped + (context.arg1 as u64).saturating_sub(heap_start),
+        _ => mapped + heap + context.arg2 as u64,
+    }
+}
+
+// NUL-terminated path from user memory
+fn read_user_path(addr: usize) -> Result<String, usize> {
+    use crate::memory::userspace::validate_user_buffer;
+
+    const MAX_PATH: usize = 4096;
+    let mut bytes = Vec::new();
+    while bytes.len() < MAX_PATH {
+        let byte_addr = addr + bytes.len();
+        let user_addr = VirtAddr::try_new(byte_addr as u64).map_err(|_| EFAULT)?;
+        if !validate_user_buffer(user_addr, 1) {
+            return Err(EFAULT);
+        }
+        let byte = unsafe { *(byte_addr as *const u8) };
+        if byte == 0 {
+            return String::from_utf8(bytes).map_err(|_| EINVAL);
+        }
+        bytes.push(byte);
+    }
+    Err(EINVAL)
+}
+
 fn dispatch_syscall(context: SyscallContext) -> Result<usize, usize> {
     use crate::memory::userspace::validate_user_buffer;
     
@@ -139,10 +404,41 @@ fn dispatch_syscall(context: SyscallContext) -> Result<usize, usize> {
         12 => handlers::sys_munmap(context.arg1, context.arg2),
         13 => handlers::sys_sleep(context.arg1),
         14 => handlers::sys_gettime(),
-        100 => handlers::sys_create_window(context.arg1, context.arg2, context.arg3, context.arg4),
+        15 => handlers::sys_ioctl(context.arg1, context.arg2, context.arg3),
+        16 => handlers::sys_unshare(context.arg1),
+        17 => handlers::sys_setns(context.arg1, context.arg2),
+        18 => handlers::sys_sethostname(context.arg1, context.arg2),
+        19 => handlers::sys_gethostname(context.arg1, context.arg2),
+        20 => handlers::sys_mount(context.arg1, context.arg2),
+        21 => handlers::sys_group_create(context.arg1, context.arg2),
+        22 => handlers::sys_group_set(context.arg1, context.arg2, context.arg3),
+        23 => handlers::sys_group_join(context.arg1),
+        24 => handlers::sys_group_destroy(context.arg1),
+        25 => handlers::sys_syslog(context.arg1, context.arg2, context.arg3),
+        26 => handlers::sys_msync(context.arg1, context.arg2, context.arg3),
+        27 => handlers::sys_vfork(),
+        28 => handlers::sys_spawn(context.arg1, context.arg2),
+        29 => handlers::sys_sched_setaffinity(context.arg1, context.arg2),
+        30 => handlers::sys_sched_getaffinity(context.arg1),
+        31 => handlers::sys_cpu_set_online(context.arg1, context.arg2),
+        32 => handlers::sys_cpu_times(context.arg1, context.arg2),
+        33 => handlers::sys_ioprio_set(context.arg1, context.arg2),
+        34 => handlers::sys_ioprio_get(context.arg1),
+        35 => handlers::sys_listen(context.arg1),
+        36 => handlers::sys_connect(context.arg1, context.arg2),
+        37 => handlers::sys_accept(context.arg1),
+        38 => handlers::sys_io_uring_setup(context.arg1, context.arg2),
+        39 => handlers::sys_io_uring_enter(context.arg1, context.arg2, context.arg3, context.arg4, context.arg5),
+        40 => handlers::sys_cet_control(context.arg1, context.arg2),
+        41 => handlers::sys_shutdown(),
+        42 => handlers::sys_reboot(),
+        43 => handlers::sys_thread_create(context.arg1, context.arg2, context.arg3),
+        44 => handlers::sys_thread_exit(context.arg1),
+        45 => handlers::sys_thread_join(context.arg1),
+        100 => handlers::sys_create_window(context.arg1, context.arg2, context.arg3, context.arg4, context.arg5),
         101 => handlers::sys_destroy_window(context.arg1),
         102 => handlers::sys_draw_window(context.arg1, context.arg2),
-        103 => handlers::sys_handle_event(context.arg1),
+        103 => handlers::sys_handle_event(context.arg1, context.arg2),
         104 => handlers::sys_get_screen_info(context.arg1),
         _ => Err(38),
     }
@@ -182,4 +478,9 @@ pub const EMLINK: usize = 31;
 pub const EPIPE: usize = 32;
 pub const EDOM: usize = 33;
 pub const ERANGE: usize = 34;
-pub const ENOSYS: usize = 38;
\ No newline at end of file
+pub const ENAMETOOLONG: usize = 36;
+pub const ENOSYS: usize = 38;
+pub const ENOTSOCK: usize = 88;
+pub const EADDRINUSE: usize = 98;
+pub const ECONNRESET: usize = 104;
+pub const ENOTCONN: usize = 107;
\ No newline at end of file
@@ -26,6 +26,43 @@ pub fn run_filesystem_tests(runner: &mut TestRunner) {
         Ok(())
     });
     
+    runner.run_test("fs::stream_paths", || {
+        // Alternate data streams are named after a colon in the last component
+        let paths = vec![
+            ("/dl/setup.exe:Zone.Identifier", "/dl/setup.exe", Some("Zone.Identifier")),
+            ("/dl/setup.exe:Zone.Identifier:$DATA", "/dl/setup.exe", Some("Zone.Identifier")),
+            ("/dl/setup.exe::$DATA", "/dl/setup.exe", None),
+            ("/dl/setup.exe", "/dl/setup.exe", None),
+            ("/dl:old/setup.exe", "/dl:old/setup.exe", None),
+        ];
+        
+        for (path, file, stream) in paths {
+            if crate::fs::split_stream(path) != (file, stream) {
+                return Err(format!("Stream path '{}' split wrongly", path));
+            }
+        }
+        
+        Ok(())
+    });
+    
+    runner.run_test("fs::ranged_reads", || {
+        // A read past the end comes up short, and one from beyond it is empty
+        let data = [1u8, 2, 3, 4, 5];
+        let mut buffer = [0u8; 4];
+        
+        if crate::fs::copy_at(&data, 0, &mut buffer) != 4 || buffer != [1, 2, 3, 4] {
+            return Err(String::from("Read from the start went wrong"));
+        }
+        if crate::fs::copy_at(&data, 3, &mut buffer) != 2 || buffer[..2] != [4, 5] {
+            return Err(String::from("Read up to the end went wrong"));
+        }
+        if crate::fs::copy_at(&data, 9, &mut buffer) != 0 {
+            return Err(String::from("Read past the end returned data"));
+        }
+        
+        Ok(())
+    });
+    
     runner.run_test("fs::inode_operations", || {
         // Test inode structure and operations
         let inode = Inode {
@@ -454,4 +454,44 @@ pub fn run_demand_paging_tests(runner: &mut TestRunner) {
         
         Ok(())
     });
-}
\ No newline at end of file
+    
+    runner.run_test("demand::process_mapping", || {
+        // A page mmap'd into a real process is filled in by its first
+        // fault, and gone once unmapped
+        use crate::memory::demand_paging::{self, PAGE_FAULT_USER, PAGE_FAULT_WRITE};
+        use crate::memory::mmap::{self, Backing};
+        use crate::memory::paging::translate_current;
+        use x86_64::VirtAddr;
+        
+        let pid = crate::process::executor::EXECUTOR.lock().allocate_pid();
+        let prot = mmap::PROT_READ | mmap::PROT_WRITE;
+        let flags = mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS;
+        let result = (|| {
+            let page = mmap::map(pid, VirtAddr::zero(), 4096, prot, flags, Backing::Anonymous)
+                .map_err(|error| format!("mmap failed: {:?}", error))?;
+            if mmap::mapping_at(pid, page) != Some(page..page + 4096u64) {
+                return Err(format!("No mapping recorded at {:#x}", page.as_u64()));
+            }
+            if translate_current(page).is_some() {
+                return Err(format!("Page present before its first touch"));
+            }
+            
+            demand_paging::handle_page_fault(page, PAGE_FAULT_WRITE | PAGE_FAULT_USER)
+                .map_err(|error| format!("Fault on the mapping failed: {}", error))?;
+            if translate_current(page).is_none() {
+                return Err(format!("Page not present after the fault"));
+            }
+            
+            mmap::unmap(pid, page, 4096).map_err(|error| format!("munmap failed: {:?}", error))?;
+            if mmap::mapping_at(pid, page).is_some() || translate_current(page).is_some() {
+                return Err(format!("Page still mapped after munmap"));
+            }
+            if demand_paging::handle_page_fault(page, PAGE_FAULT_USER).is_ok() {
+                return Err(format!("Fault on an unmapped page succeeded"));
+            }
+            Ok(())
+        })();
+        mmap::release_process(pid);
+        result
+    });
+}
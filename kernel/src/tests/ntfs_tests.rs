@@ -360,6 +360,40 @@ mod tests {
         assert!(decompress_result.is_ok(), "Failed to disable compression");
     }
     
+    #[test]
+    fn test_lznt1_round_trip() {
+        use crate::fs::ntfs::lznt1;
+        
+        // Repetitive text compresses; random bytes are stored as they are
+        let text: Vec<u8> = b"NTFS compressed folder ".iter().cycle().take(65536).copied().collect();
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..10000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        
+        let packed = lznt1::compress(&text);
+        assert!(packed.len() < text.len() / 4, "Repetitive data did not compress");
+        assert_eq!(lznt1::decompress(&packed, text.len()).unwrap(), text, "LZNT1 round trip mismatch");
+        
+        let packed = lznt1::compress(&noise);
+        assert_eq!(lznt1::decompress(&packed, noise.len()).unwrap(), noise, "Stored chunks mismatch");
+    }
+    
+    #[test]
+    fn test_data_run_encoding() {
+        use crate::fs::ntfs::attributes::{encode_data_runs, lcns_to_runs, runs_to_lcns};
+        
+        // A run, a hole, then a run behind the first
+        let lcns = [Some(100), Some(101), None, None, Some(40)];
+        let runs = lcns_to_runs(&lcns);
+        assert_eq!(runs.len(), 3, "Runs not merged");
+        assert_eq!(runs_to_lcns(&runs), lcns.to_vec(), "Runs do not map back");
+        
+        let encoded = encode_data_runs(&runs);
+        assert_eq!(encoded, vec![0x11, 2, 100, 0x01, 2, 0x11, 1, 0xC4, 0], "Mapping pairs mismatch");
+    }
+    
     #[test]
     fn test_extended_attributes() {
         let mut mock_disk = MockDiskDriver::new(100 * 1024 * 1024);
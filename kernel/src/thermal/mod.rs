@@ -55,7 +55,7 @@ pub struct CoolingDevice {
     pub min_state: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CoolingDeviceType {
     Fan,
     Processor,
@@ -72,13 +72,38 @@ pub struct ThermalManager {
     throttling_active: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ThermalPolicy {
     Performance,    // Higher temperature thresholds
     Balanced,       // Default thresholds
     Quiet,          // Lower fan speeds, more throttling
 }
 
+impl ThermalPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            ThermalPolicy::Performance => "performance",
+            ThermalPolicy::Balanced => "balanced",
+            ThermalPolicy::Quiet => "quiet",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ThermalPolicy::Performance, ThermalPolicy::Balanced, ThermalPolicy::Quiet]
+            .into_iter()
+            .find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A copy of the thermal manager's state, for `thermal status`
+#[derive(Debug, Clone)]
+pub struct ThermalStatus {
+    pub policy: ThermalPolicy,
+    pub throttling_active: bool,
+    pub zones: Vec<ThermalZone>,
+    pub cooling_devices: Vec<CoolingDevice>,
+}
+
 impl ThermalManager {
     pub fn new() -> Self {
         Self {
@@ -444,6 +469,33 @@ impl ThermalManager {
     pub fn get_thermal_status(&self) -> Vec<(String, i32)> {
         self.zones.iter().map(|z| (z.name.clone(), z.current_temp)).collect()
     }
+    
+    pub fn status(&self) -> ThermalStatus {
+        ThermalStatus {
+            policy: self.thermal_policy,
+            throttling_active: self.throttling_active,
+            zones: self.zones.clone(),
+            cooling_devices: self.cooling_devices.clone(),
+        }
+    }
+    
+    /// Set a fan to a percentage of its range. It holds until a trip
+    /// point next changes it. Returns the state it was set to.
+    pub fn set_fan_percent(&mut self, fan_id: u32, percent: u32) -> Result<u32, &'static str> {
+        if percent > 100 {
+            return Err("Fan speed must be 0 to 100 percent");
+        }
+        let device = self.cooling_devices.iter_mut()
+            .find(|d| d.id == fan_id)
+            .ok_or("No such cooling device")?;
+        if device.device_type != CoolingDeviceType::Fan {
+            return Err("Cooling device is not a fan");
+        }
+        let state = device.min_state + (device.max_state - device.min_state) * percent / 100;
+        device.current_state = state;
+        self.set_fan_speed(fan_id, state)?;
+        Ok(state)
+    }
 }
 
 lazy_static! {
@@ -466,6 +518,14 @@ pub fn get_thermal_status() -> Vec<(String, i32)> {
     THERMAL_MGR.lock().get_thermal_status()
 }
 
+pub fn status() -> ThermalStatus {
+    THERMAL_MGR.lock().status()
+}
+
+pub fn set_fan_percent(fan_id: u32, percent: u32) -> Result<u32, &'static str> {
+    THERMAL_MGR.lock().set_fan_percent(fan_id, percent)
+}
+
 pub fn detect_thermal_zones() -> Result<Vec<super::power::thermal::ThermalZone>, &'static str> {
     // Convert internal zones to power module format
     let zones = THERMAL_MGR.lock().zones.clone();
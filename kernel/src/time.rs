@@ -1,13 +0,0 @@
-use core::sync::atomic::{AtomicU64, Ordering};
-
-static SYSTEM_TIME: AtomicU64 = AtomicU64::new(0);
-
-pub fn get_timestamp() -> u64 {
-    // In a real implementation, this would read from a hardware timer
-    // For now, return a simple counter
-    SYSTEM_TIME.fetch_add(1, Ordering::SeqCst)
-}
-
-pub fn current_time_millis() -> u64 {
-    get_timestamp() * 1000
-}
\ No newline at end of file
@@ -0,0 +1,202 @@
+//! Clocksources
+//!
+//! A clocksource is a counter running at a known rate. The kernel's
+//! nanosecond timeline, `now_ns`, reads the selected one and scales the
+//! cycles it has advanced since a base point. The base is moved forward
+//! regularly, which keeps narrow counters like a 32-bit HPET from wrapping
+//! between reads, and when the source changes, which keeps the timeline
+//! monotonic. Readers run lock-free against a sequence count; updates are
+//! serialized by a lock and skipped, not waited for, when it is taken.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::sync::Mutex;
+
+// Rate the periodic timer is programmed for
+const JIFFIES_HZ: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// Timer interrupts counted since boot
+    Jiffies,
+    /// HPET main counter
+    Hpet,
+    /// Invariant TSC
+    Tsc,
+    /// Clock the hypervisor keeps for the guest (kvmclock, reference TSC page)
+    Paravirt,
+}
+
+impl ClockSource {
+    pub const ALL: [ClockSource; 4] =
+        [ClockSource::Jiffies, ClockSource::Hpet, ClockSource::Tsc, ClockSource::Paravirt];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Jiffies => "jiffies",
+            ClockSource::Hpet => "hpet",
+            ClockSource::Tsc => "tsc",
+            ClockSource::Paravirt => "paravirt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|source| source.name() == name)
+    }
+
+    /// Preference among the available sources, highest first. The
+    /// hypervisor's clock beats the TSC, which may change rate when the
+    /// guest migrates.
+    pub fn rating(&self) -> u32 {
+        match self {
+            ClockSource::Jiffies => 1,
+            ClockSource::Hpet => 250,
+            ClockSource::Tsc => 300,
+            ClockSource::Paravirt => 400,
+        }
+    }
+
+    pub fn available(&self) -> bool {
+        match self {
+            ClockSource::Jiffies => true,
+            ClockSource::Hpet => super::hpet::present(),
+            ClockSource::Tsc => super::tsc::invariant(),
+            ClockSource::Paravirt => crate::paravirt::clock_ns().is_some(),
+        }
+    }
+
+    /// Counts per second
+    pub fn frequency(&self) -> u64 {
+        match self {
+            ClockSource::Jiffies => JIFFIES_HZ,
+            ClockSource::Hpet => super::hpet::frequency(),
+            ClockSource::Tsc => super::tsc::frequency(),
+            ClockSource::Paravirt => 1_000_000_000,
+        }
+    }
+
+    /// Bits of the counter that are significant before it wraps
+    fn mask(&self) -> u64 {
+        match self {
+            ClockSource::Hpet => super::hpet::counter_mask(),
+            _ => u64::MAX,
+        }
+    }
+
+    fn read(&self) -> u64 {
+        match self {
+            ClockSource::Jiffies => crate::timer::get_ticks(),
+            ClockSource::Hpet => super::hpet::counter(),
+            ClockSource::Tsc => crate::timer::rdtsc(),
+            ClockSource::Paravirt => crate::paravirt::clock_ns().unwrap_or(0),
+        }
+    }
+}
+
+// Timeline state; odd SEQUENCE means an update is in progress
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+static FREQUENCY: AtomicU64 = AtomicU64::new(JIFFIES_HZ);
+static BASE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+
+fn load_source() -> ClockSource {
+    ClockSource::ALL[SOURCE.load(Ordering::Relaxed) as usize]
+}
+
+// Selected source, base point and the cycles read now
+fn snapshot() -> (ClockSource, u64, u64, u64, u64) {
+    loop {
+        let sequence = SEQUENCE.load(Ordering::Acquire);
+        if sequence & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let source = load_source();
+        let frequency = FREQUENCY.load(Ordering::Relaxed);
+        let base_cycles = BASE_CYCLES.load(Ordering::Relaxed);
+        let base_ns = BASE_NS.load(Ordering::Relaxed);
+        let cycles = source.read();
+        if SEQUENCE.load(Ordering::Acquire) == sequence {
+            return (source, frequency, base_cycles, base_ns, cycles);
+        }
+    }
+}
+
+fn elapsed_ns(cycles: u64, frequency: u64) -> u64 {
+    (cycles as u128 * 1_000_000_000 / frequency.max(1) as u128) as u64
+}
+
+/// Nanoseconds since the clocksource timeline started
+pub fn now_ns() -> u64 {
+    let (source, frequency, base_cycles, base_ns, cycles) = snapshot();
+    let delta = cycles.wrapping_sub(base_cycles) & source.mask();
+    // More than a second since the base moved; move it before a narrow
+    // counter can wrap past it
+    if delta > frequency {
+        update();
+    }
+    base_ns + elapsed_ns(delta, frequency)
+}
+
+// Start a new base point on `source` at the current time. Interrupts stay
+// off while the sequence is odd, or a reader in an interrupt handler
+// would spin on it forever.
+fn rebase(source: ClockSource) {
+    let index = ClockSource::ALL.iter().position(|&s| s == source).unwrap() as u8;
+    let frequency = source.frequency();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = now_ns_unchecked();
+        SEQUENCE.fetch_add(1, Ordering::AcqRel);
+        SOURCE.store(index, Ordering::Relaxed);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        BASE_CYCLES.store(source.read(), Ordering::Relaxed);
+        BASE_NS.store(now, Ordering::Relaxed);
+        SEQUENCE.fetch_add(1, Ordering::Release);
+    });
+}
+
+fn now_ns_unchecked() -> u64 {
+    let (source, frequency, base_cycles, base_ns, cycles) = snapshot();
+    base_ns + elapsed_ns(cycles.wrapping_sub(base_cycles) & source.mask(), frequency)
+}
+
+// Move the base point up to now
+fn update() {
+    if let Some(_guard) = UPDATE_LOCK.try_lock() {
+        rebase(load_source());
+    }
+}
+
+/// Switch to the best-rated source available
+pub fn init() {
+    let best = ClockSource::ALL
+        .iter()
+        .copied()
+        .filter(ClockSource::available)
+        .max_by_key(ClockSource::rating)
+        .unwrap_or(ClockSource::Jiffies);
+    let _guard = UPDATE_LOCK.lock();
+    rebase(best);
+    crate::serial_println!("[TIME] Clocksource {} at {} Hz", best.name(), best.frequency());
+}
+
+pub fn current() -> ClockSource {
+    load_source()
+}
+
+pub fn available() -> Vec<ClockSource> {
+    ClockSource::ALL.iter().copied().filter(ClockSource::available).collect()
+}
+
+/// Switch to `source`; the timeline carries on from where the old one was
+pub fn select(source: ClockSource) -> Result<(), &'static str> {
+    if !source.available() {
+        return Err("clocksource not available");
+    }
+    let _guard = UPDATE_LOCK.lock();
+    rebase(source);
+    Ok(())
+}
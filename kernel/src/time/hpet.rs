@@ -0,0 +1,193 @@
+//! High Precision Event Timer
+//!
+//! The HPET is a free-running main counter at a fixed rate of at least
+//! 10 MHz plus a set of comparators. The counter serves as a clocksource and
+//! as the reference the TSC is calibrated against. One comparator that can
+//! deliver its interrupt as an MSI (FSB delivery) becomes the event device
+//! for high-resolution timers; comparators routed through the I/O APIC are
+//! not used, since the kernel does not program it.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// Vector the event comparator's MSI arrives on
+pub const HPET_VECTOR: u8 = 0xf2;
+
+// Where the block sits on PC chipsets, used when ACPI has not said
+const DEFAULT_BASE: u64 = 0xFED0_0000;
+
+// Registers, as offsets from the base
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+const fn timer_configuration(n: u8) -> u64 {
+    0x100 + 0x20 * n as u64
+}
+const fn timer_comparator(n: u8) -> u64 {
+    0x108 + 0x20 * n as u64
+}
+const fn timer_fsb_route(n: u8) -> u64 {
+    0x110 + 0x20 * n as u64
+}
+
+// General capabilities: counter period in femtoseconds in the top half
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_TIMER_COUNT_SHIFT: u64 = 8;
+// The specification caps the period at 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const CONF_ENABLE: u64 = 1 << 0;
+// Legacy replacement routing would take over the PIT and RTC interrupts
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TN_INT_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_32BIT_MODE: u64 = 1 << 8;
+const TN_FSB_ENABLE: u64 = 1 << 14;
+const TN_FSB_CAPABLE: u64 = 1 << 15;
+
+const NO_EVENT_TIMER: u8 = u8::MAX;
+
+static BASE: AtomicU64 = AtomicU64::new(DEFAULT_BASE);
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+static TIMERS: AtomicU8 = AtomicU8::new(0);
+static EVENT_TIMER: AtomicU8 = AtomicU8::new(NO_EVENT_TIMER);
+
+fn read(offset: u64) -> u64 {
+    unsafe { ((BASE.load(Ordering::Relaxed) + offset) as *const u64).read_volatile() }
+}
+
+fn write(offset: u64, value: u64) {
+    unsafe { ((BASE.load(Ordering::Relaxed) + offset) as *mut u64).write_volatile(value) }
+}
+
+/// Base address from the ACPI HPET table
+pub fn set_base(address: u64) {
+    if address != 0 && !PRESENT.load(Ordering::Acquire) {
+        BASE.store(address, Ordering::Relaxed);
+    }
+}
+
+/// Find the HPET, start its main counter and pick an event comparator
+pub fn init() -> bool {
+    let capabilities = read(GENERAL_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if capabilities == u64::MAX || period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        crate::serial_println!("[TIME] No HPET at {:#x}", BASE.load(Ordering::Relaxed));
+        return false;
+    }
+    let timers = ((capabilities >> CAP_TIMER_COUNT_SHIFT) & 0x1f) as u8 + 1;
+
+    // Halt the counter while the comparators are quiesced, then leave it
+    // running for good
+    let configuration = read(GENERAL_CONFIGURATION) & !(CONF_ENABLE | CONF_LEGACY_ROUTE);
+    write(GENERAL_CONFIGURATION, configuration);
+    let mut event_timer = NO_EVENT_TIMER;
+    for n in 0..timers {
+        let timer = read(timer_configuration(n));
+        write(timer_configuration(n), timer & !(TN_INT_ENABLE | TN_PERIODIC | TN_FSB_ENABLE));
+        if event_timer == NO_EVENT_TIMER && timer & TN_FSB_CAPABLE != 0 {
+            event_timer = n;
+        }
+    }
+    write(GENERAL_CONFIGURATION, configuration | CONF_ENABLE);
+
+    PERIOD_FS.store(period_fs, Ordering::Relaxed);
+    COUNTER_64BIT.store(capabilities & CAP_COUNTER_64BIT != 0, Ordering::Relaxed);
+    TIMERS.store(timers, Ordering::Relaxed);
+    EVENT_TIMER.store(event_timer, Ordering::Relaxed);
+    PRESENT.store(true, Ordering::Release);
+
+    if event_timer != NO_EVENT_TIMER {
+        // Edge-triggered MSI to the boot CPU's local APIC
+        let apic_id = (core::arch::x86_64::__cpuid(1).ebx >> 24) as u64;
+        let address = 0xFEE0_0000 | (apic_id << 12);
+        write(timer_fsb_route(event_timer), (address << 32) | HPET_VECTOR as u64);
+    }
+
+    crate::serial_println!(
+        "[TIME] HPET at {:#x}: {} Hz, {}-bit counter, {} comparators, event comparator {}",
+        BASE.load(Ordering::Relaxed),
+        frequency(),
+        if capabilities & CAP_COUNTER_64BIT != 0 { 64 } else { 32 },
+        timers,
+        if event_timer == NO_EVENT_TIMER { "none" } else { "MSI" }
+    );
+    true
+}
+
+pub fn present() -> bool {
+    PRESENT.load(Ordering::Acquire)
+}
+
+/// Where the registers are, as the ACPI table gave it
+pub fn base() -> u64 {
+    BASE.load(Ordering::Relaxed)
+}
+
+/// Main counter value, within `counter_mask`
+pub fn counter() -> u64 {
+    read(MAIN_COUNTER) & counter_mask()
+}
+
+pub fn counter_mask() -> u64 {
+    if COUNTER_64BIT.load(Ordering::Relaxed) {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    }
+}
+
+/// Counter ticks per second
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => 0,
+        period_fs => 1_000_000_000_000_000 / period_fs,
+    }
+}
+
+/// Whether a comparator can interrupt for high-resolution timers
+pub fn has_event_timer() -> bool {
+    present() && EVENT_TIMER.load(Ordering::Relaxed) != NO_EVENT_TIMER
+}
+
+/// Raise HPET_VECTOR once `delay_ns` from now. False if there is no event
+/// comparator, or the deadline passed before the comparator was armed, in
+/// which case no interrupt will come.
+pub fn program_oneshot(delay_ns: u64) -> bool {
+    let n = EVENT_TIMER.load(Ordering::Relaxed);
+    if !present() || n == NO_EVENT_TIMER {
+        return false;
+    }
+
+    // Comparators match on the low 32 bits in 32-bit mode, so keep the
+    // distance well inside half a wrap
+    let period_fs = PERIOD_FS.load(Ordering::Relaxed);
+    let ticks = ((delay_ns as u128 * 1_000_000 / period_fs as u128) as u64).clamp(1, i32::MAX as u64);
+    let configuration = read(timer_configuration(n)) & !TN_PERIODIC;
+    write(timer_configuration(n), configuration | TN_32BIT_MODE | TN_FSB_ENABLE | TN_INT_ENABLE);
+
+    let now = read(MAIN_COUNTER) as u32;
+    let deadline = now.wrapping_add(ticks as u32);
+    write(timer_comparator(n), deadline as u64);
+
+    // A counter already at or past the comparator never matches it again
+    // until it wraps
+    let elapsed = (read(MAIN_COUNTER) as u32).wrapping_sub(now);
+    (elapsed as u64) < ticks
+}
+
+/// Disarm the event comparator
+pub fn stop() {
+    let n = EVENT_TIMER.load(Ordering::Relaxed);
+    if present() && n != NO_EVENT_TIMER {
+        let configuration = read(timer_configuration(n));
+        write(timer_configuration(n), configuration & !TN_INT_ENABLE);
+    }
+}
+
+/// Comparator count, for the shell
+pub fn comparators() -> u8 {
+    TIMERS.load(Ordering::Relaxed)
+}
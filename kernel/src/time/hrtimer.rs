@@ -0,0 +1,153 @@
+//! High-resolution timers
+//!
+//! One-shot timers with deadlines on the clocksource timeline, in
+//! nanoseconds. Pending timers are kept ordered by deadline and the
+//! earliest is programmed into the HPET event comparator, whose interrupt
+//! runs the expired ones. Without an event comparator, or with interrupts
+//! off, expiry is caught by the periodic tick and by the idle loop calling
+//! `run_expired`, so a timer may fire up to a tick late.
+//!
+//! Callbacks run with no lock held but possibly in interrupt context, so
+//! they must not block; a callback may start new timers.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::clocksource::now_ns;
+use crate::sync::Mutex;
+
+/// Called with the timer's data word once the deadline has passed
+pub type Callback = fn(u64);
+
+// The comparator needs a little time to be armed before its deadline
+const MIN_DELTA_NS: u64 = 2_000;
+
+struct Timer {
+    callback: Callback,
+    data: u64,
+}
+
+struct Timers {
+    next_id: u64,
+    // Keyed by deadline, then id, so equal deadlines fire in start order
+    queue: BTreeMap<(u64, u64), Timer>,
+    deadlines: BTreeMap<u64, u64>,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    next_id: 1,
+    queue: BTreeMap::new(),
+    deadlines: BTreeMap::new(),
+});
+
+static EXPIRED: AtomicU64 = AtomicU64::new(0);
+
+// The lock is taken in the HPET and timer interrupts, so holders keep
+// interrupts off
+fn with_timers<R>(f: impl FnOnce(&mut Timers) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut TIMERS.lock()))
+}
+
+// Arm the event comparator for the earliest deadline, or disarm it
+fn program(timers: &Timers) {
+    let Some(&(expires, _)) = timers.queue.keys().next() else {
+        super::hpet::stop();
+        return;
+    };
+    let mut delta = expires.saturating_sub(now_ns()).max(MIN_DELTA_NS);
+    // If the deadline slips past while arming, try a little later; the
+    // tick picks the timer up should that keep failing
+    for _ in 0..4 {
+        if super::hpet::program_oneshot(delta) {
+            return;
+        }
+        delta *= 2;
+    }
+}
+
+/// Start a timer for the absolute time `expires_ns`, returning its id
+pub fn start(expires_ns: u64, callback: Callback, data: u64) -> u64 {
+    with_timers(|timers| {
+        let id = timers.next_id;
+        timers.next_id += 1;
+        let earliest = timers.queue.keys().next().is_none_or(|&(first, _)| expires_ns < first);
+        timers.queue.insert((expires_ns, id), Timer { callback, data });
+        timers.deadlines.insert(id, expires_ns);
+        if earliest {
+            program(timers);
+        }
+        id
+    })
+}
+
+/// Start a timer `delay_ns` from now
+pub fn start_after(delay_ns: u64, callback: Callback, data: u64) -> u64 {
+    start(now_ns().saturating_add(delay_ns), callback, data)
+}
+
+/// Stop a pending timer; false if it already fired or never existed
+pub fn cancel(id: u64) -> bool {
+    with_timers(|timers| match timers.deadlines.remove(&id) {
+        Some(expires) => {
+            timers.queue.remove(&(expires, id));
+            true
+        }
+        None => false,
+    })
+}
+
+/// Run every timer whose deadline has passed and arm the comparator for
+/// the next one, returning how many ran
+pub fn run_expired() -> usize {
+    let now = now_ns();
+    let expired: Vec<Timer> = with_timers(|timers| {
+        let pending = timers.queue.split_off(&(now + 1, 0));
+        let expired = core::mem::replace(&mut timers.queue, pending);
+        for &(_, id) in expired.keys() {
+            timers.deadlines.remove(&id);
+        }
+        if !expired.is_empty() {
+            program(timers);
+        }
+        expired.into_values().collect()
+    });
+
+    for timer in &expired {
+        (timer.callback)(timer.data);
+    }
+    EXPIRED.fetch_add(expired.len() as u64, Ordering::Relaxed);
+    expired.len()
+}
+
+/// Wait in the kernel until `deadline_ns`, running timers that expire
+/// meanwhile
+pub fn sleep_until(deadline_ns: u64) {
+    while now_ns() < deadline_ns {
+        run_expired();
+        if x86_64::instructions::interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub fn sleep_ns(ns: u64) {
+    sleep_until(now_ns().saturating_add(ns));
+}
+
+/// Deadline of the earliest pending timer
+pub fn next_expiry() -> Option<u64> {
+    with_timers(|timers| timers.queue.keys().next().map(|&(expires, _)| expires))
+}
+
+/// Pending timers as (id, deadline), earliest first, for the shell
+pub fn pending() -> Vec<(u64, u64)> {
+    with_timers(|timers| timers.queue.keys().map(|&(expires, id)| (id, expires)).collect())
+}
+
+/// Timers fired since boot
+pub fn expired_count() -> u64 {
+    EXPIRED.load(Ordering::Relaxed)
+}
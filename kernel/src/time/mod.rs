@@ -0,0 +1,240 @@
+//! Kernel time
+//!
+//! Two clocks are kept. The monotonic clock is the clocksource timeline:
+//! nanoseconds since boot, never set and never jumping. The realtime clock
+//! is UTC since the Unix epoch, held as an offset from the monotonic one,
+//! so setting it moves the offset and leaves timers and sleeps alone. It
+//! starts from the CMOS RTC, which setting it also updates. Local time is
+//! realtime shifted by the time zone in `zone`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub mod clocksource;
+pub mod hpet;
+pub mod hrtimer;
+pub mod rtc;
+pub mod tsc;
+pub mod zone;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 86_400;
+// FILETIME counts 100 ns intervals from 1601-01-01
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+static SYSTEM_TIME: AtomicU64 = AtomicU64::new(0);
+// Realtime minus monotonic time
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The RTC keeps local time rather than UTC, as Windows leaves it
+static RTC_LOCAL: AtomicBool = AtomicBool::new(false);
+
+/// A calendar date and time, in UTC or local time depending on its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+pub fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+pub fn days_in_month(year: i64, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days from 1970-01-01 to a proleptic Gregorian date, after Howard Hinnant's
+// days_from_civil
+pub(crate) fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// From seconds since the Unix epoch
+    pub fn from_unix(secs: i64, millisecond: u16) -> Self {
+        let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+        let secs_of_day = secs.rem_euclid(SECS_PER_DAY);
+        DateTime {
+            year: year.clamp(0, u16::MAX as i64) as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            millisecond,
+        }
+    }
+
+    /// Seconds since the Unix epoch; milliseconds are dropped
+    pub fn to_unix(&self) -> i64 {
+        days_from_civil(self.year as i64, self.month, self.day) * SECS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// Day of the week, 0 for Sunday
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        (days_from_civil(self.year as i64, self.month, self.day) + 4).rem_euclid(7) as u8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year as i64, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+            && self.millisecond < 1000
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Nanoseconds since boot; only ever moves forward
+pub fn monotonic_ns() -> u64 {
+    clocksource::now_ns()
+}
+
+/// Nanoseconds since the Unix epoch, UTC
+pub fn realtime_ns() -> u64 {
+    REALTIME_OFFSET.load(Ordering::Relaxed).wrapping_add(monotonic_ns())
+}
+
+/// Set the realtime clock and the RTC; the monotonic clock is untouched.
+/// The realtime clock is set even if the RTC cannot be.
+pub fn set_realtime_ns(ns: u64) -> Result<(), &'static str> {
+    REALTIME_OFFSET.store(ns.wrapping_sub(monotonic_ns()), Ordering::Relaxed);
+    let secs = (ns / NS_PER_SEC) as i64;
+    let rtc_secs = if rtc_local() { zone::current().to_local(secs) } else { secs };
+    rtc::write(&DateTime::from_unix(rtc_secs, 0))
+}
+
+fn now(secs: i64, ns: u64) -> DateTime {
+    DateTime::from_unix(secs, (ns % NS_PER_SEC / 1_000_000) as u16)
+}
+
+pub fn now_utc() -> DateTime {
+    let ns = realtime_ns();
+    now((ns / NS_PER_SEC) as i64, ns)
+}
+
+pub fn now_local() -> DateTime {
+    let ns = realtime_ns();
+    now(zone::current().to_local((ns / NS_PER_SEC) as i64), ns)
+}
+
+/// Set the clock from a UTC calendar time
+pub fn set_utc(time: &DateTime) -> Result<(), &'static str> {
+    if !time.is_valid() || time.year < 1970 {
+        return Err("invalid date");
+    }
+    set_realtime_ns(time.to_unix() as u64 * NS_PER_SEC + time.millisecond as u64 * 1_000_000)
+}
+
+/// Set the clock from a local calendar time
+pub fn set_local(time: &DateTime) -> Result<(), &'static str> {
+    if !time.is_valid() {
+        return Err("invalid date");
+    }
+    let utc = zone::current().to_utc(time.to_unix());
+    set_utc(&DateTime { millisecond: time.millisecond, ..DateTime::from_unix(utc, 0) })
+}
+
+/// Realtime as a Windows FILETIME, in 100 ns intervals since 1601
+pub fn filetime_now() -> u64 {
+    unix_ns_to_filetime(realtime_ns())
+}
+
+pub fn unix_ns_to_filetime(ns: u64) -> u64 {
+    ns / 100 + FILETIME_UNIX_EPOCH
+}
+
+/// Unix nanoseconds of a FILETIME, None before 1970
+pub fn filetime_to_unix_ns(filetime: u64) -> Option<u64> {
+    filetime.checked_sub(FILETIME_UNIX_EPOCH)?.checked_mul(100)
+}
+
+pub fn rtc_local() -> bool {
+    RTC_LOCAL.load(Ordering::Relaxed)
+}
+
+/// Whether the RTC keeps local time; the clock is read back from it
+pub fn set_rtc_local(local: bool) {
+    RTC_LOCAL.store(local, Ordering::Relaxed);
+    sync_from_rtc();
+}
+
+// Start the realtime clock from the RTC
+fn sync_from_rtc() {
+    let Some(time) = rtc::read() else {
+        crate::serial_println!("[TIME] RTC unreadable, realtime starts at the epoch");
+        return;
+    };
+    let secs = if rtc_local() { zone::current().to_utc(time.to_unix()) } else { time.to_unix() };
+    let ns = (secs.max(0) as u64) * NS_PER_SEC;
+    REALTIME_OFFSET.store(ns.wrapping_sub(monotonic_ns()), Ordering::Relaxed);
+    crate::serial_println!("[TIME] RTC reads {}{}", time, if rtc_local() { " local" } else { " UTC" });
+}
+
+pub fn get_timestamp() -> u64 {
+    // In a real implementation, this would read from a hardware timer
+    // For now, return a simple counter
+    SYSTEM_TIME.fetch_add(1, Ordering::SeqCst)
+}
+
+pub fn current_time_millis() -> u64 {
+    get_timestamp() * 1000
+}
+
+/// Bring up the HPET, work out the TSC frequency against it, pick the
+/// clocksource and start the realtime clock from the RTC
+pub fn init() {
+    hpet::init();
+    let frequency = tsc::frequency();
+    crate::serial_println!(
+        "[TIME] TSC at {} Hz from {}, {}invariant",
+        frequency,
+        tsc::source().map_or("unknown", |source| source.name()),
+        if tsc::invariant() { "" } else { "not " }
+    );
+    clocksource::init();
+    sync_from_rtc();
+}
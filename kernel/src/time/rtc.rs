@@ -0,0 +1,185 @@
+//! CMOS real-time clock
+//!
+//! The RTC keeps the date across power cycles with one-second resolution,
+//! in BCD or binary and in 12- or 24-hour form as status register B says.
+//! The year register holds two digits; the century comes from the CMOS
+//! register the FADT names, and without one years are taken to fall
+//! between 1970 and 2069. A read is repeated until two agree, so an update
+//! landing in the middle cannot tear it.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use super::DateTime;
+use crate::sync::Mutex;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const B_SET: u8 = 1 << 7;
+const B_BINARY: u8 = 1 << 2;
+const B_24_HOUR: u8 = 1 << 1;
+const HOUR_PM: u8 = 1 << 7;
+
+// Give up on an RTC that never finishes updating or never reads the same
+// twice; an update takes under 2 ms
+const MAX_ATTEMPTS: usize = 10;
+const UPDATE_SPINS: usize = 100_000;
+
+// No century register
+const NO_CENTURY: u8 = 0;
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(NO_CENTURY);
+// Selecting a register and accessing it are two port writes
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// Century register from the FADT, 0 if it has none
+pub fn set_century_register(register: u8) {
+    CENTURY_REGISTER.store(register, Ordering::Relaxed);
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(register);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = CMOS.lock();
+        f()
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn wait_for_update() -> bool {
+    (0..UPDATE_SPINS).any(|_| {
+        let idle = read_register(STATUS_A) & A_UPDATE_IN_PROGRESS == 0;
+        core::hint::spin_loop();
+        idle
+    })
+}
+
+// Seconds, minutes, hours, day, month, year, century
+fn read_raw(century: u8) -> Option<[u8; 7]> {
+    if !wait_for_update() {
+        return None;
+    }
+    let century = if century == NO_CENTURY { 0 } else { read_register(century) };
+    Some([
+        read_register(SECONDS),
+        read_register(MINUTES),
+        read_register(HOURS),
+        read_register(DAY),
+        read_register(MONTH),
+        read_register(YEAR),
+        century,
+    ])
+}
+
+/// The RTC's date and time, None if it cannot be read or reads nonsense
+pub fn read() -> Option<DateTime> {
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    let (raw, status) = with_cmos(|| {
+        let mut last = read_raw(century_register)?;
+        for _ in 0..MAX_ATTEMPTS {
+            let raw = read_raw(century_register)?;
+            if raw == last {
+                return Some((raw, read_register(STATUS_B)));
+            }
+            last = raw;
+        }
+        None
+    })?;
+
+    let decode = |value: u8| if status & B_BINARY != 0 { value } else { from_bcd(value) };
+    let [second, minute, hour, day, month, year, century] = raw;
+    let mut hour_value = decode(hour & !HOUR_PM);
+    if status & B_24_HOUR == 0 {
+        hour_value = hour_value % 12 + if hour & HOUR_PM != 0 { 12 } else { 0 };
+    }
+    let year = decode(year) as u16;
+    let year = if century_register != NO_CENTURY {
+        decode(century) as u16 * 100 + year
+    } else if year < 70 {
+        2000 + year
+    } else {
+        1900 + year
+    };
+
+    let time = DateTime {
+        year,
+        month: decode(month),
+        day: decode(day),
+        hour: hour_value,
+        minute: decode(minute),
+        second: decode(second),
+        millisecond: 0,
+    };
+    time.is_valid().then_some(time)
+}
+
+/// Set the RTC, in the format it is already using
+pub fn write(time: &DateTime) -> Result<(), &'static str> {
+    if !time.is_valid() {
+        return Err("invalid date");
+    }
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    if century_register == NO_CENTURY && !(1970..2070).contains(&time.year) {
+        return Err("year out of the RTC's range");
+    }
+
+    with_cmos(|| {
+        let status = read_register(STATUS_B);
+        let encode = |value: u8| if status & B_BINARY != 0 { value } else { to_bcd(value) };
+        let hour = if status & B_24_HOUR != 0 {
+            encode(time.hour)
+        } else {
+            let twelve = match time.hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            encode(twelve) | if time.hour >= 12 { HOUR_PM } else { 0 }
+        };
+
+        // SET holds the clock still while it is written
+        write_register(STATUS_B, status | B_SET);
+        write_register(SECONDS, encode(time.second));
+        write_register(MINUTES, encode(time.minute));
+        write_register(HOURS, hour);
+        write_register(DAY, encode(time.day));
+        write_register(MONTH, encode(time.month));
+        write_register(YEAR, encode((time.year % 100) as u8));
+        if century_register != NO_CENTURY {
+            write_register(century_register, encode((time.year / 100) as u8));
+        }
+        write_register(STATUS_B, status & !B_SET);
+    });
+    Ok(())
+}
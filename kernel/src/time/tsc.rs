@@ -0,0 +1,120 @@
+//! TSC frequency and invariance
+//!
+//! The TSC is the cheapest clock to read, but only an invariant TSC (one
+//! that ticks at a constant rate through P- and C-state changes) can keep
+//! time. Its frequency is taken, in order of preference, from the
+//! hypervisor, from CPUID leaf 0x15, by calibrating against the HPET, or
+//! by calibrating against the PIT. It is worked out once and cached.
+
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+// CPUID 0x8000_0007 EDX
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+const CALIBRATION_RUNS: usize = 5;
+const CALIBRATION_NS: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Hypervisor,
+    Cpuid,
+    Hpet,
+    Pit,
+}
+
+impl Source {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Hypervisor => "hypervisor",
+            Source::Cpuid => "CPUID",
+            Source::Hpet => "HPET calibration",
+            Source::Pit => "PIT calibration",
+        }
+    }
+}
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// Source index + 1, 0 while not worked out
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+
+const SOURCES: [Source; 4] = [Source::Hypervisor, Source::Cpuid, Source::Hpet, Source::Pit];
+
+pub fn invariant() -> bool {
+    let max_extended = core::arch::x86_64::__cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && core::arch::x86_64::__cpuid(0x8000_0007).edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// TSC ticks per second
+pub fn frequency() -> u64 {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let (frequency, source) = calibrate();
+            FREQUENCY.store(frequency, Ordering::Relaxed);
+            SOURCE.store(SOURCES.iter().position(|&s| s == source).unwrap() as u8 + 1, Ordering::Relaxed);
+            frequency
+        }
+        frequency => frequency,
+    }
+}
+
+/// Where the cached frequency came from
+pub fn source() -> Option<Source> {
+    match SOURCE.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(SOURCES[n as usize - 1]),
+    }
+}
+
+/// Work out the frequency afresh
+pub fn calibrate() -> (u64, Source) {
+    if let Some(frequency) = crate::paravirt::tsc_frequency() {
+        return (frequency, Source::Hypervisor);
+    }
+    if let Some(frequency) = cpuid_frequency() {
+        return (frequency, Source::Cpuid);
+    }
+    if let Some(frequency) = hpet_frequency() {
+        return (frequency, Source::Hpet);
+    }
+    (crate::timer::calibrate_tsc(), Source::Pit)
+}
+
+// Leaf 0x15 gives the TSC/crystal ratio, and the crystal frequency on
+// parts that enumerate it
+fn cpuid_frequency() -> Option<u64> {
+    if core::arch::x86_64::__cpuid(0).eax < 0x15 {
+        return None;
+    }
+    let leaf = core::arch::x86_64::__cpuid(0x15);
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+// Count TSC ticks across a few 10 ms windows of the HPET counter and take
+// the median, so one run stretched by an SMI does not skew the result
+fn hpet_frequency() -> Option<u64> {
+    if !super::hpet::present() {
+        return None;
+    }
+    let hpet_frequency = super::hpet::frequency();
+    let mask = super::hpet::counter_mask();
+    let window = hpet_frequency * CALIBRATION_NS / 1_000_000_000;
+
+    let mut runs = [0u64; CALIBRATION_RUNS];
+    for run in runs.iter_mut() {
+        let (hpet_start, tsc_start) = (super::hpet::counter(), crate::timer::rdtsc());
+        let (hpet_end, tsc_end) = loop {
+            let (hpet, tsc) = (super::hpet::counter(), crate::timer::rdtsc());
+            if hpet.wrapping_sub(hpet_start) & mask >= window {
+                break (hpet, tsc);
+            }
+            core::hint::spin_loop();
+        };
+        let hpet_ticks = hpet_end.wrapping_sub(hpet_start) & mask;
+        *run = ((tsc_end - tsc_start) as u128 * hpet_frequency as u128 / hpet_ticks as u128) as u64;
+    }
+    runs.sort_unstable();
+    Some(runs[CALIBRATION_RUNS / 2])
+}
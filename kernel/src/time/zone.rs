@@ -0,0 +1,267 @@
+//! Time zones
+//!
+//! A zone is a standard offset from UTC and, optionally, a daylight saving
+//! rule, written as a POSIX TZ string such as "CET-1CEST,M3.5.0,M10.5.0/3":
+//! the standard name and its offset west of UTC, then the DST name with an
+//! optional offset (an hour ahead by default) and when DST starts and ends.
+//! `Mm.w.d` is day `d` (0 is Sunday) of week `w` of month `m`, week 5 being
+//! the last, and the time after `/` is local and defaults to 02:00. Zones
+//! can also be picked by name from a short built-in list.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{days_from_civil, days_in_month, DateTime};
+use crate::sync::Mutex;
+
+const SECS_PER_HOUR: i32 = 3600;
+const DEFAULT_TRANSITION_SECS: i32 = 2 * SECS_PER_HOUR;
+
+/// Named zones and their rules
+pub const ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("US/Eastern", "EST5EDT,M3.2.0,M11.1.0"),
+    ("US/Central", "CST6CDT,M3.2.0,M11.1.0"),
+    ("US/Mountain", "MST7MDT,M3.2.0,M11.1.0"),
+    ("US/Arizona", "MST7"),
+    ("US/Pacific", "PST8PDT,M3.2.0,M11.1.0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Moscow", "MSK-3"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+];
+
+/// When DST starts or ends: a weekday of some week of a month, at a local
+/// time of day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub month: u8,
+    /// 1 to 4, or 5 for the last
+    pub week: u8,
+    pub weekday: u8,
+    pub time_secs: i32,
+}
+
+impl Transition {
+    // Seconds since the epoch at which it happens in `year`, with `offset_secs`
+    // in force just before
+    fn at(&self, year: i64, offset_secs: i32) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let first_weekday = (first + 4).rem_euclid(7) as u8;
+        let mut day = 1 + (self.weekday + 7 - first_weekday) % 7 + 7 * (self.week - 1);
+        while day > days_in_month(year, self.month) {
+            day -= 7;
+        }
+        let local = (first + day as i64 - 1) * 86_400 + self.time_secs as i64;
+        local - offset_secs as i64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DstRule {
+    pub name: String,
+    /// Offset east of UTC while DST is in force
+    pub offset_secs: i32,
+    pub start: Transition,
+    pub end: Transition,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    /// The rule it was made from
+    pub spec: String,
+    pub name: String,
+    /// Standard offset east of UTC
+    pub offset_secs: i32,
+    pub dst: Option<DstRule>,
+}
+
+struct Parser<'a> {
+    spec: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.spec.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn number(&mut self) -> Option<i32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        core::str::from_utf8(&self.spec[start..self.pos]).ok()?.parse().ok()
+    }
+
+    // Alphabetic, or anything inside angle brackets
+    fn name(&mut self) -> Option<String> {
+        let quoted = self.eat(b'<');
+        let start = self.pos;
+        while self.peek().is_some_and(|byte| if quoted { byte != b'>' } else { byte.is_ascii_alphabetic() }) {
+            self.pos += 1;
+        }
+        let name = core::str::from_utf8(&self.spec[start..self.pos]).ok()?;
+        if quoted && !self.eat(b'>') || name.len() < 3 {
+            return None;
+        }
+        Some(String::from(name))
+    }
+
+    // [+-]hh[:mm[:ss]] in seconds
+    fn time(&mut self) -> Option<i32> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let mut secs = self.number()? * SECS_PER_HOUR;
+        if self.eat(b':') {
+            secs += self.number()? * 60;
+            if self.eat(b':') {
+                secs += self.number()?;
+            }
+        }
+        Some(sign * secs)
+    }
+
+    fn transition(&mut self) -> Option<Transition> {
+        if !self.eat(b'M') {
+            return None;
+        }
+        let month = self.number()?;
+        let week = self.eat(b'.').then(|| self.number())??;
+        let weekday = self.eat(b'.').then(|| self.number())??;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            return None;
+        }
+        let time_secs = if self.eat(b'/') { self.time()? } else { DEFAULT_TRANSITION_SECS };
+        Some(Transition { month: month as u8, week: week as u8, weekday: weekday as u8, time_secs })
+    }
+}
+
+impl TimeZone {
+    pub const fn utc() -> Self {
+        TimeZone { spec: String::new(), name: String::new(), offset_secs: 0, dst: None }
+    }
+
+    /// Parse a POSIX TZ string; only `Mm.w.d` transition dates are known
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parser = Parser { spec: spec.as_bytes(), pos: 0 };
+        let name = parser.name()?;
+        // POSIX offsets are west of UTC
+        let offset_secs = -parser.time()?;
+        let dst = if parser.peek().is_some() {
+            let name = parser.name()?;
+            let dst_offset = if parser.peek().is_some_and(|byte| byte != b',') {
+                -parser.time()?
+            } else {
+                offset_secs + SECS_PER_HOUR
+            };
+            if !parser.eat(b',') {
+                return None;
+            }
+            let start = parser.transition()?;
+            if !parser.eat(b',') {
+                return None;
+            }
+            let end = parser.transition()?;
+            Some(DstRule { name, offset_secs: dst_offset, start, end })
+        } else {
+            None
+        };
+        if parser.peek().is_some() {
+            return None;
+        }
+        Some(TimeZone { spec: String::from(spec), name, offset_secs, dst })
+    }
+
+    /// A zone from the built-in list, or from a POSIX TZ string
+    pub fn lookup(name: &str) -> Option<Self> {
+        match ZONES.iter().find(|(zone, _)| zone.eq_ignore_ascii_case(name)) {
+            Some((_, spec)) => Self::parse(spec),
+            None => Self::parse(name),
+        }
+    }
+
+    /// Whether DST is in force at `utc_secs`
+    pub fn is_dst(&self, utc_secs: i64) -> bool {
+        let Some(dst) = &self.dst else {
+            return false;
+        };
+        let year = DateTime::from_unix(utc_secs + self.offset_secs as i64, 0).year as i64;
+        let start = dst.start.at(year, self.offset_secs);
+        let end = dst.end.at(year, dst.offset_secs);
+        if start < end {
+            (start..end).contains(&utc_secs)
+        } else {
+            // Southern hemisphere: DST spans the new year
+            !(end..start).contains(&utc_secs)
+        }
+    }
+
+    /// Offset east of UTC in force at `utc_secs`
+    pub fn offset_at(&self, utc_secs: i64) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_dst(utc_secs) => dst.offset_secs,
+            _ => self.offset_secs,
+        }
+    }
+
+    pub fn abbreviation_at(&self, utc_secs: i64) -> &str {
+        match &self.dst {
+            Some(dst) if self.is_dst(utc_secs) => &dst.name,
+            _ if self.name.is_empty() => "UTC",
+            _ => &self.name,
+        }
+    }
+
+    pub fn to_local(&self, utc_secs: i64) -> i64 {
+        utc_secs + self.offset_at(utc_secs) as i64
+    }
+
+    /// UTC of a local time. A time repeated when DST ends is taken as the
+    /// later one; a time skipped when it starts comes out an hour late.
+    pub fn to_utc(&self, local_secs: i64) -> i64 {
+        let standard = local_secs - self.offset_secs as i64;
+        match &self.dst {
+            Some(dst) => {
+                let daylight = local_secs - dst.offset_secs as i64;
+                if self.is_dst(daylight) && self.is_dst(standard) {
+                    daylight
+                } else {
+                    standard
+                }
+            }
+            None => standard,
+        }
+    }
+}
+
+static ZONE: Mutex<TimeZone> = Mutex::new(TimeZone::utc());
+
+pub fn current() -> TimeZone {
+    ZONE.lock().clone()
+}
+
+pub fn set(zone: TimeZone) {
+    *ZONE.lock() = zone;
+}
+
+pub fn names() -> Vec<&'static str> {
+    ZONES.iter().map(|(name, _)| *name).collect()
+}
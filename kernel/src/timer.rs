@@ -1,5 +1,5 @@
 use x86_64::instructions::port::Port;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
 use raw_cpuid::CpuId;
 use alloc::collections::BinaryHeap;
@@ -13,11 +13,13 @@ const APIC_TIMER_LVT: u32 = 0x320;
 const APIC_TIMER_INITIAL_COUNT: u32 = 0x380;
 const APIC_TIMER_CURRENT_COUNT: u32 = 0x390;
 const APIC_TIMER_DIVIDE_CONFIG: u32 = 0x3E0;
+const APIC_LVT_MASKED: u32 = 1 << 16;
 
 // PIT (Programmable Interval Timer) constants for calibration
 const PIT_FREQUENCY: u32 = 1193182; // Hz
 const PIT_CHANNEL0_DATA: u16 = 0x40;
 const PIT_COMMAND: u16 = 0x43;
+const PIC1_DATA: u16 = 0x21;
 
 lazy_static! {
     pub static ref TIMER: Mutex<Timer> = Mutex::new(Timer::new());
@@ -64,6 +66,8 @@ pub struct TicklessTimer {
     min_sleep_ns: u64,
     tsc_frequency: u64,
     hpet_enabled: bool,
+    // hrtimer armed for the next deadline when the HPET is in use
+    hrtimer: Option<u64>,
     per_cpu_timers: Vec<BinaryHeap<TimerEvent>>,
 }
 
@@ -76,6 +80,7 @@ impl TicklessTimer {
             min_sleep_ns: 1000, // 1 microsecond minimum
             tsc_frequency: 2_000_000_000, // Default 2GHz
             hpet_enabled: false,
+            hrtimer: None,
             per_cpu_timers: Vec::new(),
         }
     }
@@ -87,10 +92,11 @@ impl TicklessTimer {
         }
         
         // Calibrate TSC frequency
-        self.tsc_frequency = calibrate_tsc();
+        self.tsc_frequency = get_tsc_frequency();
         
-        // Try to enable HPET
-        if let Ok(()) = init_hpet() {
+        // One-shot deadlines go through the hrtimer queue when the HPET
+        // can interrupt for it
+        if crate::time::hpet::has_event_timer() {
             self.hpet_enabled = true;
             crate::serial_println!("HPET enabled for high-resolution timers");
         }
@@ -190,7 +196,7 @@ impl TicklessTimer {
         self.program_next_interrupt();
     }
     
-    fn program_next_interrupt(&self) {
+    fn program_next_interrupt(&mut self) {
         let current_tsc = rdtsc();
         let cpu_id = crate::cpu::get_cpu_id() as usize;
         
@@ -214,7 +220,12 @@ impl TicklessTimer {
             if delay_ns > self.min_sleep_ns {
                 // Program one-shot timer
                 if self.hpet_enabled {
-                    program_hpet_oneshot(delay_ns);
+                    if let Some(id) = self.hrtimer.take() {
+                        crate::time::hrtimer::cancel(id);
+                    }
+                    self.hrtimer = Some(crate::time::hrtimer::start_after(delay_ns, tickless_expired, 0));
+                } else if crate::paravirt::hyperv::program_oneshot(crate::interrupts::PIC_1_OFFSET, delay_ns) {
+                    // Hyper-V synthetic timer, no APIC writes to trap
                 } else {
                     program_apic_oneshot(delay_tsc);
                 }
@@ -241,6 +252,12 @@ impl TicklessTimer {
     }
 }
 
+fn tickless_expired(_: u64) {
+    if let Some(mut timer) = TICKLESS_TIMER.try_lock() {
+        timer.handle_tick();
+    }
+}
+
 // Timer coalescing for power efficiency
 pub struct TimerCoalescing {
     window_ns: u64,
@@ -273,6 +290,8 @@ pub struct Timer {
     uptime_ticks: u64,
     apic_available: bool,
     apic_frequency: u32,
+    // Clocksource time and tick count when the tick was suspended
+    suspended: Option<(u64, u64)>,
 }
 
 // Global tick counter for monitoring
@@ -283,6 +302,24 @@ pub fn get_ticks() -> u64 {
     SYSTEM_TICKS.load(AtomicOrdering::Relaxed)
 }
 
+/// Start the tick on an application processor, at the rate the boot CPU
+/// calibrated. Each CPU has its own APIC timer at the same address.
+pub fn init_ap_timer() {
+    let timer = TIMER.lock();
+    if timer.apic_available && timer.apic_frequency != 0 {
+        timer.start_apic_timer();
+    }
+}
+
+/// Stop this CPU's APIC timer, as when it goes offline
+pub fn stop_ap_timer() {
+    unsafe {
+        let apic_base = APIC_BASE as *mut u32;
+        apic_base.add((APIC_TIMER_LVT / 4) as usize).write_volatile(APIC_LVT_MASKED);
+        apic_base.add((APIC_TIMER_INITIAL_COUNT / 4) as usize).write_volatile(0);
+    }
+}
+
 impl Timer {
     const fn new() -> Self {
         Self {
@@ -290,10 +327,18 @@ impl Timer {
             uptime_ticks: 0,
             apic_available: false,
             apic_frequency: 0,
+            suspended: None,
         }
     }
     
     pub fn init(&mut self) {
+        // Under Hyper-V a synthetic timer ticks without emulated hardware
+        if crate::paravirt::hyperv::start_periodic_timer(crate::interrupts::PIC_1_OFFSET, 10_000_000) {
+            self.ticks_per_second = 100;
+            crate::serial_println!("Timer: Hyper-V synthetic timer initialized at {} Hz", self.ticks_per_second);
+            return;
+        }
+
         // Check if APIC is available
         if self.detect_apic() {
             self.init_apic_timer();
@@ -319,10 +364,15 @@ impl Timer {
     }
     
     fn init_apic_timer(&mut self) {
+        // Calibrate APIC timer using PIT
+        self.apic_frequency = self.calibrate_apic_timer();
+        self.ticks_per_second = 100;
+        self.start_apic_timer();
+    }
+
+    // Start this CPU's APIC timer ticking at the calibrated rate
+    fn start_apic_timer(&self) {
         unsafe {
-            // Calibrate APIC timer using PIT
-            self.apic_frequency = self.calibrate_apic_timer();
-            
             // Configure APIC timer for periodic mode
             let apic_base = APIC_BASE as *mut u32;
             
@@ -332,15 +382,18 @@ impl Timer {
             // Set timer LVT entry (vector 32, periodic mode)
             apic_base.add((APIC_TIMER_LVT / 4) as usize).write_volatile(0x20020);
             
-            // Set initial count for desired frequency (100Hz)
-            let initial_count = self.apic_frequency / 100;
+            // Set initial count for desired frequency
+            let initial_count = self.apic_frequency / self.ticks_per_second as u32;
             apic_base.add((APIC_TIMER_INITIAL_COUNT / 4) as usize).write_volatile(initial_count);
-            
-            self.ticks_per_second = 100;
         }
     }
     
     fn calibrate_apic_timer(&self) -> u32 {
+        // Hyper-V reports the frequency outright
+        if let Some(frequency) = crate::paravirt::hyperv::apic_frequency() {
+            return frequency as u32;
+        }
+
         unsafe {
             let apic_base = APIC_BASE as *mut u32;
             
@@ -386,8 +439,49 @@ impl Timer {
     
     pub fn tick(&mut self) {
         self.uptime_ticks += 1;
+        SYSTEM_TICKS.fetch_add(1, AtomicOrdering::Relaxed);
     }
     
+    // Mask the tick at its source: the APIC timer's LVT entry, or IRQ 0 on
+    // the master PIC
+    fn mask_tick(&self, masked: bool) {
+        unsafe {
+            if self.apic_available {
+                let lvt = (APIC_BASE as *mut u32).add((APIC_TIMER_LVT / 4) as usize);
+                let entry = lvt.read_volatile();
+                lvt.write_volatile(if masked { entry | APIC_LVT_MASKED } else { entry & !APIC_LVT_MASKED });
+            } else {
+                let mut mask_port = Port::<u8>::new(PIC1_DATA);
+                let mask = mask_port.read();
+                mask_port.write(if masked { mask | 0x01 } else { mask & !0x01 });
+            }
+        }
+    }
+
+    /// Stop the periodic tick while the CPU idles tickless; something else
+    /// (an hrtimer) must be armed to wake it
+    pub fn suspend_tick(&mut self) {
+        if self.suspended.is_none() {
+            self.mask_tick(true);
+            self.suspended = Some((crate::time::clocksource::now_ns(), self.uptime_ticks));
+        }
+    }
+
+    /// Restart the tick, counting the ticks that would have come meanwhile.
+    /// A tick source that could not be masked will have kept counting, so
+    /// only the ticks still missing are added.
+    pub fn resume_tick(&mut self) {
+        let Some((since, ticks)) = self.suspended.take() else {
+            return;
+        };
+        self.mask_tick(false);
+        let elapsed_ns = crate::time::clocksource::now_ns().saturating_sub(since);
+        let expected = (elapsed_ns as u128 * self.ticks_per_second as u128 / 1_000_000_000) as u64;
+        let missed = expected.saturating_sub(self.uptime_ticks - ticks);
+        self.uptime_ticks += missed;
+        SYSTEM_TICKS.fetch_add(missed, AtomicOrdering::Relaxed);
+    }
+
     pub fn get_uptime_ms(&self) -> u64 {
         (self.uptime_ticks * 1000) / self.ticks_per_second
     }
@@ -413,13 +507,13 @@ pub fn rdtsc() -> u64 {
     }
 }
 
-// Get TSC frequency (calibrated)
+// Get TSC frequency (calibrated once, then cached)
 pub fn get_tsc_frequency() -> u64 {
-    calibrate_tsc()
+    crate::time::tsc::frequency()
 }
 
 // Calibrate TSC frequency using PIT
-fn calibrate_tsc() -> u64 {
+pub(crate) fn calibrate_tsc() -> u64 {
     unsafe {
         // Use PIT channel 2 for calibration
         let mut cmd_port = Port::<u8>::new(0x43);
@@ -455,49 +549,6 @@ fn calibrate_tsc() -> u64 {
     }
 }
 
-// HPET (High Precision Event Timer) support
-const HPET_BASE: u64 = 0xFED00000;
-
-struct HpetRegisters {
-    capabilities: u64,
-    configuration: u64,
-    interrupt_status: u64,
-    _reserved: u64,
-    main_counter: u64,
-}
-
-fn init_hpet() -> Result<(), &'static str> {
-    unsafe {
-        let hpet = HPET_BASE as *mut HpetRegisters;
-        
-        // Check if HPET is present
-        if (*hpet).capabilities == 0 || (*hpet).capabilities == u64::MAX {
-            return Err("HPET not available");
-        }
-        
-        // Enable HPET
-        (*hpet).configuration |= 0x1;
-        
-        Ok(())
-    }
-}
-
-fn program_hpet_oneshot(delay_ns: u64) {
-    unsafe {
-        let hpet = HPET_BASE as *mut HpetRegisters;
-        
-        // Convert nanoseconds to HPET ticks
-        let capabilities = (*hpet).capabilities;
-        let period_fs = capabilities >> 32; // Period in femtoseconds
-        let ticks = (delay_ns * 1_000_000) / period_fs;
-        
-        // Program comparator 0 for one-shot
-        let comparator0 = (HPET_BASE + 0x100) as *mut u64;
-        let current = (*hpet).main_counter;
-        *comparator0 = current + ticks;
-    }
-}
-
 fn program_apic_oneshot(delay_tsc: u64) {
     unsafe {
         const APIC_BASE: u64 = 0xFEE00000;
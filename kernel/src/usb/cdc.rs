@@ -0,0 +1,148 @@
+// USB Communications Device Class: what the serial and network class
+// drivers share
+//
+// A CDC function is a communication interface, which takes the class
+// requests and may report events on an interrupt endpoint, and a data
+// interface with a bulk endpoint each way. The communication interface's
+// functional descriptors say which data interface is its partner (the
+// union descriptor) and, for networking, the MAC address and largest frame.
+use super::{DeviceRequest, InterfaceInfo, UsbDevice, USB_CLASS_CDC, USB_CLASS_CDC_DATA};
+use alloc::vec::Vec;
+
+// Class-specific descriptor types
+pub const CS_INTERFACE: u8 = 0x24;
+
+// Communication interface subclasses
+pub const SUBCLASS_ACM: u8 = 0x02;
+pub const SUBCLASS_ETHERNET: u8 = 0x06;
+pub const SUBCLASS_NCM: u8 = 0x0D;
+
+// Functional descriptor subtypes
+pub const FUNC_UNION: u8 = 0x06;
+pub const FUNC_ETHERNET: u8 = 0x0F;
+
+// Class requests
+pub const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+pub const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+pub const SET_LINE_CODING: u8 = 0x20;
+pub const GET_LINE_CODING: u8 = 0x21;
+pub const SET_CONTROL_LINE_STATE: u8 = 0x22;
+pub const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+pub const GET_NTB_PARAMETERS: u8 = 0x80;
+pub const SET_NTB_INPUT_SIZE: u8 = 0x86;
+
+// SET_CONTROL_LINE_STATE bits
+pub const LINE_DTR: u16 = 0x01;
+pub const LINE_RTS: u16 = 0x02;
+
+// SET_ETHERNET_PACKET_FILTER bits
+pub const PACKET_TYPE_PROMISCUOUS: u16 = 0x01;
+pub const PACKET_TYPE_ALL_MULTICAST: u16 = 0x02;
+pub const PACKET_TYPE_DIRECTED: u16 = 0x04;
+pub const PACKET_TYPE_BROADCAST: u16 = 0x08;
+
+// Notifications on the interrupt endpoint
+pub const NOTIFY_NETWORK_CONNECTION: u8 = 0x00;
+pub const NOTIFY_RESPONSE_AVAILABLE: u8 = 0x01;
+pub const NOTIFY_SERIAL_STATE: u8 = 0x20;
+pub const NOTIFY_SPEED_CHANGE: u8 = 0x2A;
+
+// SERIAL_STATE bits
+pub const SERIAL_STATE_DCD: u16 = 0x01;
+pub const SERIAL_STATE_DSR: u16 = 0x02;
+pub const SERIAL_STATE_RING: u16 = 0x08;
+
+/// A class request to an interface
+pub fn class_request(request: u8, value: u16, interface: u8, length: u16, device_to_host: bool) -> DeviceRequest {
+    DeviceRequest {
+        // Class, interface
+        request_type: if device_to_host { 0xA1 } else { 0x21 },
+        request,
+        value,
+        index: interface as u16,
+        length,
+    }
+}
+
+/// The functional descriptors of an interface, as (subtype, body) with the
+/// body starting after the subtype
+pub fn functional_descriptors(interface: &InterfaceInfo) -> Vec<(u8, &[u8])> {
+    let data = &interface.class_descriptors;
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while offset + 3 <= data.len() {
+        let length = data[offset] as usize;
+        if length < 3 || offset + length > data.len() {
+            break;
+        }
+        if data[offset + 1] == CS_INTERFACE {
+            found.push((data[offset + 2], &data[offset + 3..offset + length]));
+        }
+        offset += length;
+    }
+    found
+}
+
+/// A CDC function: its communication interface and the data interface the
+/// union descriptor pairs it with
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub control: InterfaceInfo,
+    pub data: u8,
+}
+
+impl Function {
+    /// The first communication interface of `subclass` on the device
+    pub fn find(device: &UsbDevice, subclass: u8) -> Option<Function> {
+        let control = device
+            .interfaces
+            .iter()
+            .find(|interface| interface.class == USB_CLASS_CDC && interface.subclass == subclass)?;
+        Self::with_control(device, control)
+    }
+
+    pub fn with_control(device: &UsbDevice, control: &InterfaceInfo) -> Option<Function> {
+        let union = functional_descriptors(control)
+            .into_iter()
+            .find(|(subtype, body)| *subtype == FUNC_UNION && body.len() >= 2)
+            .map(|(_, body)| body[1]);
+        // Without a union descriptor, the next interface is the usual guess
+        let data = union.unwrap_or(control.number + 1);
+        device.interface(data)?;
+        Some(Function { control: control.clone(), data })
+    }
+
+    /// The data interface's setting that has bulk endpoints; for
+    /// networking, the first setting has none so the function stays quiet
+    /// until the host selects another
+    pub fn data_setting<'a>(&self, device: &'a UsbDevice) -> Option<&'a InterfaceInfo> {
+        device.interfaces.iter().find(|interface| {
+            interface.number == self.data
+                && interface.class == USB_CLASS_CDC_DATA
+                && interface.bulk_in().is_some()
+                && interface.bulk_out().is_some()
+        })
+    }
+
+    /// The Ethernet networking functional descriptor: the string index of
+    /// the MAC address and the largest frame
+    pub fn ethernet(&self) -> Option<(u8, u16)> {
+        functional_descriptors(&self.control)
+            .into_iter()
+            .find(|(subtype, body)| *subtype == FUNC_ETHERNET && body.len() >= 7)
+            .map(|(_, body)| (body[0], u16::from_le_bytes([body[5], body[6]])))
+    }
+}
+
+/// A MAC address as the iMACAddress string gives it: twelve hex digits
+pub fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let text = text.trim();
+    if text.len() != 12 || !text.is_ascii() {
+        return None;
+    }
+    let mut mac = [0u8; 6];
+    for (index, byte) in mac.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(mac)
+}
@@ -93,37 +93,47 @@ pub struct DeviceInfo {
 
 impl DeviceInfo {
     pub fn class_name(&self) -> &str {
-        match self.class {
-            0x01 => "Audio",
-            0x02 => "Communications",
-            0x03 => "HID",
-            0x05 => "Physical",
-            0x06 => "Image",
-            0x07 => "Printer",
-            0x08 => "Mass Storage",
-            0x09 => "Hub",
-            0x0A => "CDC Data",
-            0x0B => "Smart Card",
-            0x0D => "Content Security",
-            0x0E => "Video",
-            0x0F => "Healthcare",
-            0xDC => "Diagnostic",
-            0xE0 => "Wireless",
-            0xEF => "Miscellaneous",
-            0xFE => "Application Specific",
-            0xFF => "Vendor Specific",
-            _ => "Unknown",
-        }
+        class_name(self.class)
     }
     
     pub fn speed_name(&self) -> &str {
-        match self.speed {
-            UsbSpeed::Low => "Low (1.5 Mbps)",
-            UsbSpeed::Full => "Full (12 Mbps)",
-            UsbSpeed::High => "High (480 Mbps)",
-            UsbSpeed::Super => "Super (5 Gbps)",
-            UsbSpeed::SuperPlus => "SuperSpeed+ (10 Gbps)",
-        }
+        speed_name(self.speed)
+    }
+}
+
+/// The name of a USB device class code
+pub fn class_name(class: u8) -> &'static str {
+    match class {
+        0x00 => "Per Interface",
+        0x01 => "Audio",
+        0x02 => "Communications",
+        0x03 => "HID",
+        0x05 => "Physical",
+        0x06 => "Image",
+        0x07 => "Printer",
+        0x08 => "Mass Storage",
+        0x09 => "Hub",
+        0x0A => "CDC Data",
+        0x0B => "Smart Card",
+        0x0D => "Content Security",
+        0x0E => "Video",
+        0x0F => "Healthcare",
+        0xDC => "Diagnostic",
+        0xE0 => "Wireless",
+        0xEF => "Miscellaneous",
+        0xFE => "Application Specific",
+        0xFF => "Vendor Specific",
+        _ => "Unknown",
+    }
+}
+
+pub fn speed_name(speed: UsbSpeed) -> &'static str {
+    match speed {
+        UsbSpeed::Low => "Low (1.5 Mbps)",
+        UsbSpeed::Full => "Full (12 Mbps)",
+        UsbSpeed::High => "High (480 Mbps)",
+        UsbSpeed::Super => "Super (5 Gbps)",
+        UsbSpeed::SuperPlus => "SuperSpeed+ (10 Gbps)",
     }
 }
 
@@ -0,0 +1,438 @@
+//! USB gamepads
+//!
+//! HID gamepads and joysticks are read through their report descriptor:
+//! X and Y are the left stick, Z and Rz the right one, Rx and Ry or brake
+//! and accelerator the triggers, and the hat switch the d-pad. Buttons go
+//! by number, in the order most pads number them, or in Sony's order on a
+//! DualShock 4 or DualSense so that Cross is A. Wired Xbox 360 pads are
+//! vendor-class and send a fixed report, already in XInput's layout.
+//!
+//! Reports come in on an interrupt URB kept waiting, as `usb::hid` reads
+//! keyboards, and each becomes a `gamepad::State`. Rumble goes out on the
+//! interrupt OUT endpoint, for the pads whose motor reports are known. A
+//! pad leaves its player slot when its driver is removed, or when its
+//! reports stop because it was unplugged.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::urb::{self, Urb, UrbId, UrbStatus};
+use super::{DeviceRequest, InterfaceInfo, TransferType, USB_CLASS_HID, USB_CLASS_VENDOR};
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::input::gamepad::{
+    self, State, A, B, BACK, DPAD_DOWN, DPAD_LEFT, DPAD_RIGHT, DPAD_UP, GUIDE, LEFT_SHOULDER, LEFT_THUMB,
+    RIGHT_SHOULDER, RIGHT_THUMB, START, X, Y,
+};
+use crate::input::hid::{Field, ReportDescriptor, ReportKind};
+use crate::serial_println;
+
+// Usages, as page << 16 | ID
+const GD_JOYSTICK: u32 = 0x0001_0004;
+const GD_GAMEPAD: u32 = 0x0001_0005;
+const GD_X: u32 = 0x0001_0030;
+const GD_Y: u32 = 0x0001_0031;
+const GD_Z: u32 = 0x0001_0032;
+const GD_RX: u32 = 0x0001_0033;
+const GD_RY: u32 = 0x0001_0034;
+const GD_RZ: u32 = 0x0001_0035;
+const GD_HAT_SWITCH: u32 = 0x0001_0039;
+const SIM_ACCELERATOR: u32 = 0x0002_00C4;
+const SIM_BRAKE: u32 = 0x0002_00C5;
+const PAGE_BUTTON: u32 = 0x0009;
+
+// The HID class descriptor, and the report descriptor it gives the length of
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u16 = 0x22;
+// Read when the HID descriptor does not say
+const DEFAULT_REPORT_DESCRIPTOR_LENGTH: usize = 512;
+
+// Xbox 360 pads: the interface, and the report types on it
+const XBOX360_SUBCLASS: u8 = 0x5D;
+const XBOX360_PROTOCOL: u8 = 0x01;
+const XBOX360_INPUT: u8 = 0x00;
+const XBOX360_REPORT_LENGTH: usize = 20;
+// LED patterns 6 to 9 light the quarter for players 1 to 4
+const XBOX360_LED_PLAYER1: u8 = 0x06;
+
+const VENDOR_SONY: u16 = 0x054C;
+const DUALSHOCK4: [u16; 3] = [0x05C4, 0x09CC, 0x0BA0];
+const DUALSENSE: u16 = 0x0CE6;
+
+// Buttons by number, from button 1
+const GENERIC_BUTTONS: [u16; 11] =
+    [A, B, X, Y, LEFT_SHOULDER, RIGHT_SHOULDER, BACK, START, LEFT_THUMB, RIGHT_THUMB, GUIDE];
+// Square, Cross, Circle, Triangle, L1, R1, L2, R2, Share, Options, L3, R3,
+// PS; L2 and R2 are read as triggers instead
+const SONY_BUTTONS: [u16; 13] =
+    [X, A, B, Y, LEFT_SHOULDER, RIGHT_SHOULDER, 0, 0, BACK, START, LEFT_THUMB, RIGHT_THUMB, GUIDE];
+
+// The hat's eight directions, clockwise from up
+const HAT: [u16; 8] = [
+    DPAD_UP,
+    DPAD_UP | DPAD_RIGHT,
+    DPAD_RIGHT,
+    DPAD_DOWN | DPAD_RIGHT,
+    DPAD_DOWN,
+    DPAD_DOWN | DPAD_LEFT,
+    DPAD_LEFT,
+    DPAD_UP | DPAD_LEFT,
+];
+
+// How long a request to the pad may wait to be sent
+const REQUEST_TIMEOUT_MS: u64 = 1000;
+
+/// The output report that drives a pad's motors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Motors {
+    None,
+    DualShock4,
+    DualSense,
+    Xbox360,
+}
+
+enum Layout {
+    Hid { descriptor: ReportDescriptor, buttons: &'static [u16] },
+    Xbox360,
+}
+
+struct Pad {
+    slot: usize,
+    layout: Layout,
+    report_urb: Option<UrbId>,
+}
+
+// Pads by USB address
+static PADS: Mutex<BTreeMap<u8, Pad>> = Mutex::new(BTreeMap::new());
+
+// Scale a value in a field's logical range to -32768 to 32767
+fn stick(field: &Field, value: i32) -> i16 {
+    let range = field.logical_max as i64 - field.logical_min as i64;
+    if range <= 0 {
+        return 0;
+    }
+    let value = (value as i64).clamp(field.logical_min as i64, field.logical_max as i64);
+    ((value - field.logical_min as i64) * 65535 / range - 32768) as i16
+}
+
+// Scale a value in a field's logical range to 0 to 255
+fn trigger(field: &Field, value: i32) -> u8 {
+    let range = field.logical_max as i64 - field.logical_min as i64;
+    if range <= 0 {
+        return 0;
+    }
+    let value = (value as i64).clamp(field.logical_min as i64, field.logical_max as i64);
+    ((value - field.logical_min as i64) * 255 / range) as u8
+}
+
+// The d-pad buttons a hat switch's value means; anything out of range is
+// the hat at rest. A four-way hat counts only the straight directions.
+fn hat(field: &Field, value: i32) -> u16 {
+    let position = value - field.logical_min;
+    let positions = field.logical_max - field.logical_min + 1;
+    let step = if positions == 4 { 2 } else { 1 };
+    if !(0..positions).contains(&position) || positions > 8 {
+        return 0;
+    }
+    HAT.get((position * step) as usize).copied().unwrap_or(0)
+}
+
+// The state in a HID input report, or None if the report is not one the
+// pad's inputs are in
+fn hid_state(descriptor: &ReportDescriptor, buttons: &[u16], report: &[u8]) -> Option<State> {
+    let (id, data) = if descriptor.numbered { (*report.first()?, report.get(1..)?) } else { (0, report) };
+    let fields = descriptor.fields.iter().filter(|field| {
+        field.kind == ReportKind::Input
+            && field.report_id == id
+            && field.variable()
+            && matches!(field.application, GD_GAMEPAD | GD_JOYSTICK)
+    });
+    let mut state = State::default();
+    let mut found = false;
+    for field in fields {
+        found = true;
+        for index in 0..field.count {
+            let (usage, value) = (field.usage(index), field.value(data, index));
+            match usage {
+                GD_X => state.thumb_lx = stick(field, value),
+                GD_Y => state.thumb_ly = !stick(field, value),
+                GD_Z => state.thumb_rx = stick(field, value),
+                GD_RZ => state.thumb_ry = !stick(field, value),
+                GD_RX | SIM_BRAKE => state.left_trigger = trigger(field, value),
+                GD_RY | SIM_ACCELERATOR => state.right_trigger = trigger(field, value),
+                GD_HAT_SWITCH => state.buttons |= hat(field, value),
+                usage if usage >> 16 == PAGE_BUTTON && value != 0 => {
+                    let number = (usage & 0xFFFF) as usize;
+                    state.buttons |= number.checked_sub(1).and_then(|n| buttons.get(n)).copied().unwrap_or(0);
+                }
+                _ => {}
+            }
+        }
+    }
+    found.then_some(state)
+}
+
+// The state in an Xbox 360 pad's input report; other reports, such as
+// the LED and rumble acknowledgements, carry none
+fn xbox360_state(report: &[u8]) -> Option<State> {
+    if report.len() < XBOX360_REPORT_LENGTH || report[0] != XBOX360_INPUT {
+        return None;
+    }
+    let word = |at: usize| i16::from_le_bytes([report[at], report[at + 1]]);
+    Some(State {
+        buttons: u16::from_le_bytes([report[2], report[3]]),
+        left_trigger: report[4],
+        right_trigger: report[5],
+        thumb_lx: word(6),
+        thumb_ly: word(8),
+        thumb_rx: word(10),
+        thumb_ry: word(12),
+    })
+}
+
+fn report(address: u8, data: &[u8]) {
+    let update = {
+        let pads = PADS.lock();
+        let Some(pad) = pads.get(&address) else {
+            return;
+        };
+        let state = match &pad.layout {
+            Layout::Hid { descriptor, buttons } => hid_state(descriptor, buttons, data),
+            Layout::Xbox360 => xbox360_state(data),
+        };
+        state.map(|state| (pad.slot, state))
+    };
+    if let Some((slot, state)) = update {
+        gamepad::update(slot, state);
+    }
+}
+
+// Wait for a report. Each one that comes back is read and the URB sent
+// again; if the transfer fails the pad is gone.
+fn submit_report_urb(address: u8, endpoint: u8, size: usize) -> Result<UrbId, &'static str> {
+    urb::submit(Urb::interrupt(address, endpoint, vec![0u8; size]).on_complete(move |urb| match urb.status {
+        UrbStatus::Completed => {
+            if urb.actual > 0 {
+                report(address, urb.data());
+            }
+            let next = submit_report_urb(address, endpoint, size).ok();
+            if let Some(pad) = PADS.lock().get_mut(&address) {
+                pad.report_urb = next;
+            }
+        }
+        UrbStatus::Cancelled => {}
+        status => {
+            serial_println!("gamepad: reports from device {} stopped: {:?}", address, status);
+            detach(address);
+        }
+    }))
+}
+
+// Send an output report: on the interrupt OUT endpoint if there is one,
+// or as a SET_REPORT request
+fn send(address: u8, interface: u8, endpoint: Option<u8>, data: Vec<u8>) -> Result<(), &'static str> {
+    let transfer = match endpoint {
+        Some(endpoint) => Urb::interrupt(address, endpoint, data),
+        None => {
+            let setup = DeviceRequest {
+                // Host to device, class, interface; SET_REPORT of an output
+                // report
+                request_type: 0x21,
+                request: 0x09,
+                value: 0x0200 | data[0] as u16,
+                index: interface as u16,
+                length: data.len() as u16,
+            };
+            Urb::control(address, setup, data)
+        }
+    };
+    let transfer = transfer.with_timeout(REQUEST_TIMEOUT_MS).on_complete(move |urb| {
+        if urb.status != UrbStatus::Completed {
+            serial_println!("gamepad: output to device {} failed: {:?}", address, urb.status);
+        }
+    });
+    urb::submit(transfer).map(|_| ())
+}
+
+// The output report that sets the motors, low-frequency then high
+fn motor_report(motors: Motors, low: u16, high: u16) -> Option<Vec<u8>> {
+    let (low, high) = ((low >> 8) as u8, (high >> 8) as u8);
+    let mut data = match motors {
+        Motors::None => return None,
+        Motors::Xbox360 => return Some(vec![0x00, 0x08, 0x00, low, high, 0x00, 0x00, 0x00]),
+        // Report 5: the motors are valid, then the right (small) and left
+        // (big) ones
+        Motors::DualShock4 => {
+            let mut data = vec![0u8; 32];
+            data[..2].copy_from_slice(&[0x05, 0x01]);
+            data
+        }
+        // Report 2: compatible vibration, then right and left
+        Motors::DualSense => {
+            let mut data = vec![0u8; 63];
+            data[..2].copy_from_slice(&[0x02, 0x03]);
+            data
+        }
+    };
+    let at = if motors == Motors::DualShock4 { 4 } else { 3 };
+    data[at] = high;
+    data[at + 1] = low;
+    Some(data)
+}
+
+fn interrupt_out(interface: &InterfaceInfo) -> Option<u8> {
+    interface
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.transfer_type == TransferType::Interrupt && !endpoint.is_in())
+        .map(|endpoint| endpoint.address)
+}
+
+// The report descriptor's length, from the HID descriptor among the
+// interface's class descriptors
+fn report_descriptor_length(interface: &InterfaceInfo) -> usize {
+    let descriptors = &interface.class_descriptors;
+    let mut at = 0;
+    while at + 2 <= descriptors.len() {
+        let length = descriptors[at] as usize;
+        if length == 0 {
+            break;
+        }
+        if descriptors[at + 1] == DESCRIPTOR_HID && length >= 9 && at + 9 <= descriptors.len() {
+            return u16::from_le_bytes([descriptors[at + 7], descriptors[at + 8]]) as usize;
+        }
+        at += length;
+    }
+    DEFAULT_REPORT_DESCRIPTOR_LENGTH
+}
+
+fn read_report_descriptor(address: u8, interface: &InterfaceInfo) -> Result<ReportDescriptor, &'static str> {
+    let mut data = vec![0u8; report_descriptor_length(interface)];
+    let request = DeviceRequest {
+        // Device to host, standard, interface
+        request_type: 0x81,
+        request: 0x06,
+        value: DESCRIPTOR_REPORT << 8,
+        index: interface.number as u16,
+        length: data.len() as u16,
+    };
+    let length = crate::usb::control_transfer(address, &request, Some(&mut data))?;
+    ReportDescriptor::parse(&data[..length.min(data.len())])
+}
+
+// Take the pad at `address` and start reading it
+fn attach(
+    address: u8,
+    name: &str,
+    interface: &InterfaceInfo,
+    layout: Layout,
+    motors: Motors,
+) -> Result<(), ProbeError> {
+    let endpoint = interface.interrupt_in().ok_or(ProbeError::Failed("no interrupt IN endpoint"))?;
+    let (endpoint, size) = (endpoint.address, endpoint.max_packet_size.max(8) as usize);
+    let (number, out) = (interface.number, interrupt_out(interface));
+    let rumble: Option<gamepad::Rumble> = (motors != Motors::None).then(|| {
+        let rumble = move |low: u16, high: u16| match motor_report(motors, low, high) {
+            Some(data) => send(address, number, out, data),
+            None => Err("pad has no motors"),
+        };
+        Arc::new(rumble) as gamepad::Rumble
+    });
+    let slot = gamepad::connect(name, rumble).ok_or(ProbeError::Failed("every player slot is taken"))?;
+    PADS.lock().insert(address, Pad { slot, layout, report_urb: None });
+    match submit_report_urb(address, endpoint, size) {
+        Ok(id) => {
+            if let Some(pad) = PADS.lock().get_mut(&address) {
+                pad.report_urb = Some(id);
+            }
+        }
+        Err(e) => {
+            detach(address);
+            return Err(ProbeError::Failed(e));
+        }
+    }
+    if motors == Motors::Xbox360 {
+        if let Err(e) = send(address, number, out, vec![0x01, 0x03, XBOX360_LED_PLAYER1 + slot as u8]) {
+            serial_println!("gamepad: cannot light device {}'s LED: {}", address, e);
+        }
+    }
+    Ok(())
+}
+
+// Let the pad at `address` go
+fn detach(address: u8) {
+    let Some(pad) = PADS.lock().remove(&address) else {
+        return;
+    };
+    if let Some(id) = pad.report_urb {
+        urb::cancel(id);
+    }
+    gamepad::disconnect(pad.slot);
+}
+
+pub static USB_DRIVER: GamepadDriver = GamepadDriver;
+
+pub struct GamepadDriver;
+
+impl Driver for GamepadDriver {
+    fn name(&self) -> &'static str {
+        "gamepad"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // Not boot keyboards or mice; the descriptor says which are pads
+            Match::UsbClass { class: USB_CLASS_HID, subclass: Some(0), protocol: None },
+            // Xbox 360 pads are vendor-class throughout; probe finds the
+            // interface
+            Match::UsbClass { class: USB_CLASS_VENDOR, subclass: None, protocol: None },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, vendor, product, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let name = match usb.product.as_str() {
+            "" => String::from("USB gamepad"),
+            product => String::from(product),
+        };
+
+        let xbox360 = usb.interfaces.iter().find(|interface| {
+            interface.class == USB_CLASS_VENDOR
+                && interface.subclass == XBOX360_SUBCLASS
+                && interface.protocol == XBOX360_PROTOCOL
+        });
+        if let Some(interface) = xbox360 {
+            return attach(address, &name, interface, Layout::Xbox360, Motors::Xbox360);
+        }
+
+        let interface =
+            usb.interfaces.iter().find(|interface| interface.class == USB_CLASS_HID).ok_or(ProbeError::NoDevice)?;
+        let descriptor = read_report_descriptor(address, interface).map_err(ProbeError::Failed)?;
+        if !descriptor.has_application(GD_GAMEPAD) && !descriptor.has_application(GD_JOYSTICK) {
+            return Err(ProbeError::NoDevice);
+        }
+        let (buttons, motors): (&'static [u16], Motors) = match (vendor, product) {
+            (VENDOR_SONY, product) if DUALSHOCK4.contains(&product) => (&SONY_BUTTONS, Motors::DualShock4),
+            (VENDOR_SONY, DUALSENSE) => (&SONY_BUTTONS, Motors::DualSense),
+            _ => (&GENERIC_BUTTONS, Motors::None),
+        };
+        attach(address, &name, interface, Layout::Hid { descriptor, buttons }, motors)
+    }
+
+    fn remove(&self, device: &Device) {
+        if let Ident::Usb { address, .. } = device.ident {
+            detach(address);
+        }
+    }
+}
@@ -1,10 +1,19 @@
 // USB HID (Human Interface Device) Implementation
-use super::{UsbDevice, UsbController, DeviceRequest, EndpointInfo, TransferType};
+//
+// Requests and reports go by URB: setup requests are sent without waiting
+// on them, and each device keeps an interrupt URB waiting on its input
+// endpoint, handling the report it brings back and sending it again.
+// Keyboards and mice are input devices, and their reports become key,
+// button and motion events.
+use super::{UsbDevice, UsbController, DeviceRequest, EndpointInfo, TransferType, USB_CLASS_HID};
+use super::urb::{self, Urb, UrbId, UrbStatus};
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::{println, serial_println};
+use crate::input::{self, DeviceKind, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_KEY, EV_REL, REL_WHEEL, REL_X, REL_Y};
 
 // HID Class Specific Requests
 #[repr(u8)]
@@ -91,10 +100,15 @@ pub struct KeyboardState {
 // HID Device
 pub struct HidDevice {
     pub device: UsbDevice,
+    pub interface: u8,
     pub protocol: HidProtocol,
     pub report_descriptor: Vec<u8>,
     pub interrupt_endpoint: Option<EndpointInfo>,
     pub report_size: usize,
+    // The URB waiting for the next input report
+    pub report_urb: Option<UrbId>,
+    // The input device its reports go to
+    pub input: Option<usize>,
 }
 
 impl HidDevice {
@@ -106,13 +120,19 @@ impl HidDevice {
                 (ep.address & 0x80) != 0  // IN endpoint
             })
             .cloned();
+        let interface = device.interfaces.iter()
+            .find(|interface| interface.class == USB_CLASS_HID)
+            .map_or(0, |interface| interface.number);
         
         Self {
             device,
+            interface,
             protocol: HidProtocol::None,
             report_descriptor: Vec::new(),
             interrupt_endpoint,
             report_size: 8,  // Default
+            report_urb: None,
+            input: None,
         }
     }
     
@@ -186,17 +206,65 @@ impl HidDevice {
     
     fn set_protocol(&mut self, protocol: u8) -> Result<(), &'static str> {
         // Set boot protocol (0) or report protocol (1)
-        // This would need controller access
-        Ok(())
+        self.class_request(HidRequest::SetProtocol, protocol as u16)
     }
     
     fn set_idle(&mut self, duration: u8, report_id: u8) -> Result<(), &'static str> {
-        // Set idle rate for the device
-        // This would need controller access
+        // Set idle rate for the device, in 4 ms steps
+        self.class_request(HidRequest::SetIdle, (duration as u16) << 8 | report_id as u16)
+    }
+    
+    // Send a class request with no data to the HID interface, not waiting
+    // for it to complete
+    fn class_request(&self, request: HidRequest, value: u16) -> Result<(), &'static str> {
+        let setup = DeviceRequest {
+            request_type: 0x21,  // Host to device, class, interface
+            request: request as u8,
+            value,
+            index: self.interface as u16,
+            length: 0,
+        };
+        let address = self.device.address;
+        let transfer = Urb::control(address, setup, Vec::new())
+            .with_timeout(REQUEST_TIMEOUT_MS)
+            .on_complete(move |urb| {
+                if urb.status != UrbStatus::Completed {
+                    serial_println!("HID: {:?} to device {} failed: {:?}", request, address, urb.status);
+                }
+            });
+        urb::submit(transfer).map(|_| ())
+    }
+    
+    // Start reading input reports from the interrupt endpoint
+    fn start_reports(&mut self) -> Result<(), &'static str> {
+        let endpoint = self.interrupt_endpoint.as_ref().ok_or("HID device has no interrupt IN endpoint")?;
+        let size = self.report_size.max(endpoint.max_packet_size as usize);
+        self.report_urb = Some(submit_report_urb(self.device.address, endpoint.address, size)?);
         Ok(())
     }
 }
 
+// How long a setup request may wait to be sent
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+// Wait for an input report. Each one that comes back is handled and the URB
+// sent again; a failed transfer stops the reports.
+fn submit_report_urb(address: u8, endpoint: u8, size: usize) -> Result<UrbId, &'static str> {
+    urb::submit(Urb::interrupt(address, endpoint, vec![0u8; size]).on_complete(move |urb| match urb.status {
+        UrbStatus::Completed => {
+            if urb.actual > 0 {
+                process_hid_interrupt(address, urb.data());
+            }
+            let next = submit_report_urb(address, endpoint, size).ok();
+            if let Some(device) = HID_MANAGER.lock().devices.iter_mut().find(|d| d.device.address == address) {
+                device.report_urb = next;
+            }
+        }
+        UrbStatus::Cancelled => {}
+        status => serial_println!("HID: reports from device {} stopped: {:?}", address, status),
+    }))
+}
+
 // Mouse Report Parser (Boot Protocol)
 pub fn parse_mouse_report(data: &[u8]) -> MouseState {
     if data.len() < 3 {
@@ -302,6 +370,34 @@ pub fn hid_to_ascii(hid_code: u8, shift: bool) -> Option<char> {
     }
 }
 
+// Linux key codes of the keyboard page's usages, by usage; 0 for none
+const USAGE_KEYS: [u8; 0x66] = [
+    0, 0, 0, 0,
+    // A to Z
+    30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44,
+    // 1 to 0
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 11,
+    // Enter, Escape, Backspace, Tab, Space, then the punctuation
+    28, 1, 14, 15, 57, 12, 13, 26, 27, 43, 43, 39, 40, 41, 51, 52, 53,
+    // Caps Lock, F1 to F12
+    58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 87, 88,
+    // Print Screen to Up, Num Lock
+    99, 70, 119, 110, 102, 104, 111, 107, 109, 106, 105, 108, 103, 69,
+    // The keypad, the ISO key and Menu
+    98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71, 72, 73, 82, 83, 86, 127,
+];
+
+// The modifier byte's bits, as keys: left Ctrl, Shift, Alt and GUI, then
+// the right ones
+const MODIFIER_KEYS: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+// Usage the keyboard reports in every slot when too many keys are down
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+fn usage_key(usage: u8) -> Option<u16> {
+    USAGE_KEYS.get(usage as usize).filter(|&&key| key != 0).map(|&key| key as u16)
+}
+
 // Mouse Driver
 pub struct MouseDriver {
     current_state: MouseState,
@@ -326,8 +422,11 @@ impl MouseDriver {
         }
     }
     
-    pub fn process_report(&mut self, data: &[u8]) {
+    pub fn process_report(&mut self, device: Option<usize>, data: &[u8]) {
         let state = parse_mouse_report(data);
+        if let Some(device) = device {
+            report_motion(device, &self.current_state.buttons, &state);
+        }
         
         // Update absolute position
         self.absolute_x += (state.x as f32 * self.sensitivity) as i32;
@@ -381,8 +480,23 @@ impl KeyboardDriver {
         }
     }
     
-    pub fn process_report(&mut self, data: &[u8]) {
+    pub fn process_report(&mut self, device: Option<usize>, data: &[u8]) {
         let state = parse_keyboard_report(data);
+        // Too many keys down to say which; wait for a report that can
+        if state.pressed_keys.contains(&ERROR_ROLL_OVER) {
+            return;
+        }
+        
+        // Modifiers, from the bits that changed
+        let previous = modifier_bits(&self.current_state.modifiers);
+        let current = modifier_bits(&state.modifiers);
+        if let Some(device) = device {
+            for (bit, &key) in MODIFIER_KEYS.iter().enumerate() {
+                if (previous ^ current) & (1 << bit) != 0 {
+                    input::key(device, key, current & (1 << bit) != 0);
+                }
+            }
+        }
         
         // Detect key press events
         for &key in &state.pressed_keys {
@@ -391,12 +505,8 @@ impl KeyboardDriver {
                 if let Some(handler) = self.event_handler {
                     handler(key, true);
                 }
-                
-                // Convert to ASCII and print
-                if let Some(ch) = hid_to_ascii(key, state.modifiers.left_shift || state.modifiers.right_shift) {
-                    // This would normally go to the input buffer
-                    // For now, just log it
-                    serial_println!("HID Keyboard: '{}'", ch);
+                if let (Some(device), Some(code)) = (device, usage_key(key)) {
+                    input::key(device, code, true);
                 }
             }
         }
@@ -408,8 +518,14 @@ impl KeyboardDriver {
                 if let Some(handler) = self.event_handler {
                     handler(key, false);
                 }
+                if let (Some(device), Some(code)) = (device, usage_key(key)) {
+                    input::key(device, code, false);
+                }
             }
         }
+        if let Some(device) = device {
+            input::sync(device);
+        }
         
         // Update state
         self.current_state = state.clone();
@@ -425,6 +541,43 @@ impl KeyboardDriver {
     }
 }
 
+// The modifier byte, back from its fields
+fn modifier_bits(modifiers: &KeyboardModifiers) -> u8 {
+    [
+        modifiers.left_ctrl,
+        modifiers.left_shift,
+        modifiers.left_alt,
+        modifiers.left_gui,
+        modifiers.right_ctrl,
+        modifiers.right_shift,
+        modifiers.right_alt,
+        modifiers.right_gui,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (bit, &held)| bits | (held as u8) << bit)
+}
+
+// A mouse report as events: the buttons that changed, then the motion
+fn report_motion(device: usize, previous: &MouseButtons, state: &MouseState) {
+    let buttons = [
+        (previous.left, state.buttons.left, BTN_LEFT),
+        (previous.right, state.buttons.right, BTN_RIGHT),
+        (previous.middle, state.buttons.middle, BTN_MIDDLE),
+    ];
+    for (was, is, button) in buttons {
+        if was != is {
+            input::report(device, EV_KEY, button, is as i32);
+        }
+    }
+    for (axis, value) in [(REL_X, state.x), (REL_Y, state.y), (REL_WHEEL, state.wheel as i32)] {
+        if value != 0 {
+            input::report(device, EV_REL, axis, value);
+        }
+    }
+    input::sync(device);
+}
+
 // Global HID Manager
 pub struct HidManager {
     devices: Vec<HidDevice>,
@@ -443,6 +596,26 @@ impl HidManager {
     
     pub fn add_device(&mut self, mut device: HidDevice) -> Result<(), &'static str> {
         device.init()?;
+        // Other HID devices are read by the drivers that bind them, such
+        // as gamepads
+        if device.protocol != HidProtocol::None {
+            if let Err(e) = device.start_reports() {
+                serial_println!("HID: no reports from device {}: {}", device.device.address, e);
+            }
+        }
+        
+        let kind = match device.protocol {
+            HidProtocol::Keyboard => Some(DeviceKind::Keyboard),
+            HidProtocol::Mouse => Some(DeviceKind::Mouse),
+            HidProtocol::None => None,
+        };
+        if let Some(kind) = kind {
+            let name = match device.device.product.as_str() {
+                "" => alloc::format!("USB {}", kind.name()),
+                product => String::from(product),
+            };
+            device.input = Some(input::register(&name, kind));
+        }
         
         match device.protocol {
             HidProtocol::Mouse => {
@@ -470,12 +643,12 @@ impl HidManager {
             match device.protocol {
                 HidProtocol::Mouse => {
                     if let Some(ref mut driver) = self.mouse_driver {
-                        driver.process_report(data);
+                        driver.process_report(device.input, data);
                     }
                 }
                 HidProtocol::Keyboard => {
                     if let Some(ref mut driver) = self.keyboard_driver {
-                        driver.process_report(data);
+                        driver.process_report(device.input, data);
                     }
                 }
                 _ => {}
@@ -5,6 +5,13 @@ pub mod ehci;
 pub mod xhci;
 pub mod hub;
 pub mod device;
+pub mod cdc;
+pub mod gamepad;
+pub mod uvc;
+pub mod serial;
+pub mod usbnet;
+pub mod rtl8188eu;
+pub mod urb;
 
 use alloc::vec::Vec;
 use alloc::string::String;
@@ -165,6 +172,38 @@ pub trait UsbController: Send + Sync {
     fn bulk_transfer(&mut self, device: &UsbDevice, endpoint: u8, data: &mut [u8], is_write: bool) -> Result<usize, &'static str>;
     fn interrupt_transfer(&mut self, device: &UsbDevice, endpoint: u8, data: &mut [u8]) -> Result<usize, &'static str>;
     fn get_controller_type(&self) -> ControllerType;
+    
+    /// Carry out a URB's transfer when its endpoint's turn comes, returning
+    /// the bytes moved. By default this is the synchronous call for its
+    /// pipe; a controller that queues transfers itself does better.
+    fn submit_urb(&mut self, device: &UsbDevice, urb: &mut urb::Urb) -> Result<usize, &'static str> {
+        let is_write = urb.is_write();
+        match urb.pipe {
+            urb::Pipe::Control(request) => {
+                let length = request.length as usize;
+                let data = if length == 0 { None } else { Some(&mut urb.buffer[..length]) };
+                self.control_transfer(device, &request, data)
+            }
+            urb::Pipe::Bulk { endpoint } => self.bulk_transfer(device, endpoint, &mut urb.buffer, is_write),
+            urb::Pipe::Interrupt { endpoint } => self.interrupt_transfer(device, endpoint, &mut urb.buffer),
+            urb::Pipe::Isochronous { endpoint } => {
+                self.isochronous_transfer(device, endpoint, &mut urb.buffer, &mut urb.packets)
+            }
+        }
+    }
+    
+    /// Move an isochronous URB's packets, one per service interval, setting
+    /// each packet's length and status; returns the bytes moved. None of
+    /// the controllers here schedule isochronous transfers yet.
+    fn isochronous_transfer(
+        &mut self,
+        _device: &UsbDevice,
+        _endpoint: u8,
+        _data: &mut [u8],
+        _packets: &mut [urb::IsoPacket],
+    ) -> Result<usize, &'static str> {
+        Err("isochronous transfers not supported by this controller")
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -189,8 +228,11 @@ pub struct UsbDevice {
     pub subclass: u8,
     pub protocol: u8,
     pub endpoints: Vec<EndpointInfo>,
+    pub interfaces: Vec<InterfaceInfo>,
     pub parent_hub: Option<u8>,
     pub port: u8,
+    /// Index of the host controller the device is on
+    pub controller: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -201,6 +243,55 @@ pub struct EndpointInfo {
     pub interval: u8,
 }
 
+impl EndpointInfo {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+    
+    /// Bytes the endpoint moves each service interval: the packet size, times
+    /// the transactions a high-bandwidth endpoint makes in a microframe
+    pub fn bytes_per_interval(&self) -> usize {
+        let size = (self.max_packet_size & 0x7FF) as usize;
+        size * (1 + ((self.max_packet_size >> 11) & 3) as usize)
+    }
+}
+
+// An interface of the active configuration, one entry per alternate setting
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointInfo>,
+    /// Class-specific descriptors that follow the interface descriptor,
+    /// such as CDC's functional descriptors, as they were read
+    pub class_descriptors: Vec<u8>,
+}
+
+impl InterfaceInfo {
+    fn endpoint(&self, transfer_type: TransferType, is_in: bool) -> Option<&EndpointInfo> {
+        self.endpoints.iter().find(|ep| ep.transfer_type == transfer_type && ep.is_in() == is_in)
+    }
+    
+    pub fn bulk_in(&self) -> Option<&EndpointInfo> {
+        self.endpoint(TransferType::Bulk, true)
+    }
+    
+    pub fn bulk_out(&self) -> Option<&EndpointInfo> {
+        self.endpoint(TransferType::Bulk, false)
+    }
+    
+    pub fn interrupt_in(&self) -> Option<&EndpointInfo> {
+        self.endpoint(TransferType::Interrupt, true)
+    }
+    
+    pub fn isochronous_in(&self) -> Option<&EndpointInfo> {
+        self.endpoint(TransferType::Isochronous, true)
+    }
+}
+
 impl UsbDevice {
     pub fn new(address: u8, speed: UsbSpeed) -> Self {
         Self {
@@ -215,8 +306,10 @@ impl UsbDevice {
             subclass: 0,
             protocol: 0,
             endpoints: Vec::new(),
+            interfaces: Vec::new(),
             parent_hub: None,
             port: 0,
+            controller: 0,
         }
     }
     
@@ -231,6 +324,15 @@ impl UsbDevice {
     pub fn is_hub(&self) -> bool {
         self.class == USB_CLASS_HUB
     }
+    
+    /// The interface's first alternate setting
+    pub fn interface(&self, number: u8) -> Option<&InterfaceInfo> {
+        self.interfaces.iter().find(|interface| interface.number == number)
+    }
+    
+    pub fn interface_alternate(&self, number: u8, alternate: u8) -> Option<&InterfaceInfo> {
+        self.interfaces.iter().find(|interface| interface.number == number && interface.alternate == alternate)
+    }
 }
 
 // USB Manager
@@ -310,6 +412,7 @@ impl UsbManager {
             for mut device in devices {
                 // Assign address
                 device.address = self.next_address;
+                device.controller = i;
                 self.next_address += 1;
                 
                 // Get device descriptor
@@ -365,17 +468,22 @@ impl UsbManager {
             length: core::mem::size_of::<ConfigurationDescriptor>() as u16,
         };
         
-        let mut buffer = [0u8; 256];
+        let mut header = [0u8; core::mem::size_of::<ConfigurationDescriptor>()];
+        let len = self.controllers[controller_idx].control_transfer(device, &request, Some(&mut header))?;
+        if len < header.len() {
+            return Ok(());
+        }
+        let config = unsafe { *(header.as_ptr() as *const ConfigurationDescriptor) };
+        device.config_desc = Some(config);
+        
+        // Then the whole configuration, with its interfaces and endpoints
+        let total_length = config.total_length;
+        let mut buffer = alloc::vec![0u8; (total_length as usize).max(header.len())];
+        let request = DeviceRequest { length: buffer.len() as u16, ..request };
         let len = self.controllers[controller_idx].control_transfer(device, &request, Some(&mut buffer))?;
         
-        if len >= core::mem::size_of::<ConfigurationDescriptor>() {
-            unsafe {
-                device.config_desc = Some(*(buffer.as_ptr() as *const ConfigurationDescriptor));
-            }
-            
-            // Parse interfaces and endpoints
-            self.parse_configuration(&buffer[..len], device)?;
-        }
+        // Parse interfaces and endpoints
+        self.parse_configuration(&buffer[..len], device)?;
         
         Ok(())
     }
@@ -405,6 +513,22 @@ impl UsbManager {
                             device.subclass = interface.interface_subclass;
                             device.protocol = interface.interface_protocol;
                         }
+                        
+                        device.interfaces.push(InterfaceInfo {
+                            number: interface.interface_number,
+                            alternate: interface.alternate_setting,
+                            class: interface.interface_class,
+                            subclass: interface.interface_subclass,
+                            protocol: interface.interface_protocol,
+                            endpoints: Vec::new(),
+                            class_descriptors: Vec::new(),
+                        });
+                    }
+                }
+                cdc::CS_INTERFACE => {
+                    // Class-specific, belonging to the interface before it
+                    if let Some(interface) = device.interfaces.last_mut() {
+                        interface.class_descriptors.extend_from_slice(&data[offset..offset + length]);
                     }
                 }
                 0x05 => {
@@ -422,12 +546,16 @@ impl UsbManager {
                             _ => TransferType::Control,
                         };
                         
-                        device.endpoints.push(EndpointInfo {
+                        let info = EndpointInfo {
                             address: endpoint.endpoint_address,
                             transfer_type,
                             max_packet_size: endpoint.max_packet_size,
                             interval: endpoint.interval,
-                        });
+                        };
+                        if let Some(interface) = device.interfaces.last_mut() {
+                            interface.endpoints.push(info.clone());
+                        }
+                        device.endpoints.push(info);
                     }
                 }
                 _ => {}
@@ -514,6 +642,42 @@ impl UsbManager {
     pub fn get_hid_devices(&self) -> Vec<&UsbDevice> {
         self.devices.iter().filter(|d| d.is_hid()).collect()
     }
+    
+    pub fn device(&self, address: u8) -> Option<&UsbDevice> {
+        self.devices.iter().find(|d| d.address == address)
+    }
+    
+    // The device at `address` and the controller it is on
+    fn route(&mut self, address: u8) -> Result<(&mut Box<dyn UsbController>, &UsbDevice), &'static str> {
+        let device = self.devices.iter().find(|d| d.address == address).ok_or("No such USB device")?;
+        let controller = self.controllers.get_mut(device.controller).ok_or("USB controller gone")?;
+        Ok((controller, device))
+    }
+    
+    pub fn control(&mut self, address: u8, request: &DeviceRequest, data: Option<&mut [u8]>) -> Result<usize, &'static str> {
+        let (controller, device) = self.route(address)?;
+        controller.control_transfer(device, request, data)
+    }
+    
+    pub fn bulk(&mut self, address: u8, endpoint: u8, data: &mut [u8], is_write: bool) -> Result<usize, &'static str> {
+        let (controller, device) = self.route(address)?;
+        controller.bulk_transfer(device, endpoint, data, is_write)
+    }
+    
+    pub fn interrupt(&mut self, address: u8, endpoint: u8, data: &mut [u8]) -> Result<usize, &'static str> {
+        let (controller, device) = self.route(address)?;
+        controller.interrupt_transfer(device, endpoint, data)
+    }
+    
+    pub fn submit_urb(&mut self, urb: &mut urb::Urb) -> Result<usize, &'static str> {
+        let (controller, device) = self.route(urb.address)?;
+        controller.submit_urb(device, urb)
+    }
+    
+    pub fn string(&mut self, address: u8, index: u8) -> Result<String, &'static str> {
+        let device = self.device(address).ok_or("No such USB device")?.clone();
+        self.get_string_descriptor(device.controller, &device, index)
+    }
 }
 
 lazy_static! {
@@ -526,6 +690,40 @@ pub fn init() {
     });
 }
 
+// Transfers for class drivers, to a device by its address
+
+pub fn device(address: u8) -> Option<UsbDevice> {
+    USB_MANAGER.lock().device(address).cloned()
+}
+
+pub fn control_transfer(address: u8, request: &DeviceRequest, data: Option<&mut [u8]>) -> Result<usize, &'static str> {
+    USB_MANAGER.lock().control(address, request, data)
+}
+
+pub fn bulk_transfer(address: u8, endpoint: u8, data: &mut [u8], is_write: bool) -> Result<usize, &'static str> {
+    USB_MANAGER.lock().bulk(address, endpoint, data, is_write)
+}
+
+pub fn interrupt_transfer(address: u8, endpoint: u8, data: &mut [u8]) -> Result<usize, &'static str> {
+    USB_MANAGER.lock().interrupt(address, endpoint, data)
+}
+
+pub fn string_descriptor(address: u8, index: u8) -> Result<String, &'static str> {
+    USB_MANAGER.lock().string(address, index)
+}
+
+/// Select an interface's alternate setting
+pub fn set_interface(address: u8, interface: u8, alternate: u8) -> Result<(), &'static str> {
+    let request = DeviceRequest {
+        request_type: 0x01,  // Host to device, standard, interface
+        request: RequestType::SetInterface as u8,
+        value: alternate as u16,
+        index: interface as u16,
+        length: 0,
+    };
+    control_transfer(address, &request, None).map(|_| ())
+}
+
 // Helper functions for monitoring/diagnostics module
 pub fn enumerate_devices() -> Option<Vec<device::UsbDeviceInfo>> {
     let manager = USB_MANAGER.lock();
@@ -0,0 +1,785 @@
+//! Realtek RTL8188EU USB Wi-Fi adapters
+//!
+//! The RTL8188EUS and RTL8188ETV are 802.11n radios with an 8051 that
+//! runs Realtek's firmware; the host does the MAC. So each adapter is a
+//! `Radio` under `wireless::mac80211::SoftMac`, and this side only powers
+//! the chip up, loads its firmware and setup tables, tunes it, and moves
+//! frames on the bulk pipes behind the chip's transmit and receive
+//! descriptors.
+//!
+//! Registers are read and written with vendor request 5 on the default
+//! pipe, the address as the value. The MAC address is in the chip's
+//! eFuse. The firmware is linux-firmware's `rtlwifi/rtl8188eufw.bin`, and
+//! the MAC, baseband, AGC and radio tables are Realtek's MAC_REG.txt,
+//! PHY_REG.txt, AGC_TAB.txt and RadioA.txt, all read from /lib/firmware
+//! when the adapter is bound. Without them the adapter is left alone.
+//!
+//! The channel width is 20 MHz. Frames go at fixed rates, 1 Mb/s for
+//! management and 24 Mb/s for data, with the chip's retries; there is no
+//! rate adaptation, no HT, and no TX power or IQ calibration past what the
+//! tables set.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::net::ethernet::MacAddress;
+use crate::net::wireless::mac80211::{Radio, RxFrame, SoftMac};
+use crate::time::clocksource::now_ns;
+use crate::usb::DeviceRequest;
+
+const FIRMWARE: &str = "/lib/firmware/rtlwifi/rtl8188eufw.bin";
+const MAC_TABLE: &str = "/lib/firmware/rtl8188e/MAC_REG.txt";
+const BB_TABLE: &str = "/lib/firmware/rtl8188e/PHY_REG.txt";
+const AGC_TABLE: &str = "/lib/firmware/rtl8188e/AGC_TAB.txt";
+const RF_TABLE: &str = "/lib/firmware/rtl8188e/RadioA.txt";
+
+// Vendor request for register access
+const REALTEK_USB_REQUEST: u8 = 0x05;
+// Largest register write in one request
+const WRITE_BLOCK: usize = 128;
+
+// System registers
+const REG_SYS_ISO_CTRL: u16 = 0x0000;
+const REG_SYS_FUNC: u16 = 0x0002;
+const REG_APS_FSMCO: u16 = 0x0004;
+const REG_SYS_CLKR: u16 = 0x0008;
+const REG_9346CR: u16 = 0x000A;
+const REG_RF_CTRL: u16 = 0x001F;
+const REG_LPLDO_CTRL: u16 = 0x0023;
+const REG_AFE_XTAL_CTRL: u16 = 0x0024;
+const REG_EFUSE_CTRL: u16 = 0x0030;
+const REG_MCU_FW_DL: u16 = 0x0080;
+// MAC registers
+const REG_CR: u16 = 0x0100;
+const REG_MSR: u16 = 0x0102;
+const REG_TRXDMA_CTRL: u16 = 0x010C;
+const REG_TRXFF_BNDY: u16 = 0x0114;
+const REG_LLT_INIT: u16 = 0x01E0;
+const REG_RQPN: u16 = 0x0200;
+const REG_RQPN_NPQ: u16 = 0x0214;
+const REG_BWOPMODE: u16 = 0x0603;
+const REG_RCR: u16 = 0x0608;
+const REG_MACID: u16 = 0x0610;
+const REG_RXFLTMAP0: u16 = 0x06A0;
+const REG_RXFLTMAP2: u16 = 0x06A4;
+const REG_FW_START: u16 = 0x1000;
+// Baseband registers, and the serial interface to the radio behind them
+const REG_FPGA0_RF_MODE: u16 = 0x0800;
+const REG_FPGA0_XA_HSSI_PARM1: u16 = 0x0820;
+const REG_FPGA0_XA_HSSI_PARM2: u16 = 0x0824;
+const REG_FPGA0_XA_LSSI_PARM: u16 = 0x0840;
+const REG_FPGA0_XA_LSSI_READBACK: u16 = 0x08A0;
+const REG_HSPI_XA_READBACK: u16 = 0x08B8;
+const REG_FPGA1_RF_MODE: u16 = 0x0900;
+
+// REG_SYS_ISO_CTRL, REG_SYS_FUNC and REG_SYS_CLKR
+const SYS_ISO_PWC_EV12V: u16 = 1 << 15;
+const SYS_FUNC_BBRSTB: u16 = 1 << 0;
+const SYS_FUNC_BB_GLB_RSTN: u16 = 1 << 1;
+const SYS_FUNC_USBA: u16 = 1 << 2;
+const SYS_FUNC_USBD: u16 = 1 << 4;
+const SYS_FUNC_CPU_ENABLE: u16 = 1 << 10;
+const SYS_FUNC_ELDR: u16 = 1 << 12;
+const SYS_CLK_ANA8M: u16 = 1 << 1;
+const SYS_CLK_LOADER_ENABLE: u16 = 1 << 5;
+// REG_9346CR: the eFuse was loaded at power-up
+const EEPROM_BOOT: u16 = 1 << 4;
+const EEPROM_ENABLE: u16 = 1 << 5;
+// REG_RF_CTRL: radio enabled and out of reset
+const RF_ENABLE: u8 = 0x07;
+
+// REG_MCU_FW_DL
+const MCU_FW_DL_ENABLE: u32 = 1 << 0;
+const MCU_FW_DL_READY: u32 = 1 << 1;
+const MCU_FW_DL_CSUM_REPORT: u32 = 1 << 2;
+const MCU_WINT_INIT_READY: u32 = 1 << 6;
+const MCU_FW_RAM_SEL: u32 = 1 << 7;
+const MCU_8051_RESET: u32 = 1 << 19;
+const FW_PAGE_SIZE: usize = 4096;
+const FW_HEADER_SIZE: usize = 32;
+const FW_SIGNATURE: u16 = 0x88E0;
+
+// REG_CR: the DMA engines, the protocol and scheduler blocks, security
+// and the calibration timer
+const CR_ENABLE: u16 = 0x063F;
+const MSR_STATION: u8 = 0x02;
+// REG_TRXDMA_CTRL: every queue to the high priority pages
+const TRXDMA_ALL_HIGH: u16 = 0xFFF0;
+
+// Transmit pages, the share each queue reserves, and the end of the
+// buffer's link list; the rest of the buffer is the receive FIFO
+const TX_PAGES: u32 = 0xA8;
+const HIGH_PAGES: u32 = 0x29;
+const LOW_PAGES: u32 = 0x1C;
+const NORMAL_PAGES: u32 = 0x1C;
+const LAST_LLT_ENTRY: u32 = 175;
+const RX_FIFO_BOUNDARY: u16 = 0x25FF;
+const RQPN_LOAD: u32 = 1 << 31;
+const LLT_WRITE: u32 = 1 << 30;
+const LLT_BUSY: u32 = 3 << 30;
+
+// REG_RCR: frames to this address, multicast and broadcast, management
+// and data, with the PHY status in front
+const RCR_ACCEPT_PHYS_MATCH: u32 = 1 << 1;
+const RCR_ACCEPT_MCAST: u32 = 1 << 2;
+const RCR_ACCEPT_BCAST: u32 = 1 << 3;
+const RCR_ACCEPT_DATA: u32 = 1 << 11;
+const RCR_ACCEPT_MGMT: u32 = 1 << 13;
+const RCR_APPEND_PHYSTAT: u32 = 1 << 28;
+
+// Radio registers
+const RF_MODE_AG: u8 = 0x18;
+const RF_CHANNEL_MASK: u32 = 0xFF;
+const RF_BW_20MHZ: u32 = 3 << 10;
+const RF_DATA_MASK: u32 = 0xF_FFFF;
+const HSSI_READ_ADDRESS_SHIFT: u32 = 23;
+const HSSI_READ_ADDRESS_MASK: u32 = 0xFF << HSSI_READ_ADDRESS_SHIFT;
+const HSSI_READ_EDGE: u32 = 1 << 31;
+const HSSI_PI_MODE: u32 = 1 << 8;
+const BW_OPMODE_20MHZ: u8 = 1 << 2;
+
+// The eFuse: its size, where the MAC address is once it is unpacked, and
+// the bit that says a byte has been read
+const EFUSE_PHYSICAL: usize = 256;
+const EFUSE_LOGICAL: usize = 512;
+const EFUSE_MAC: usize = 0xD7;
+const EFUSE_READ_DONE: u32 = 1 << 31;
+
+// Transmit descriptor
+const TX_DESC_SIZE: usize = 32;
+const TXDESC_BROADMULTICAST: u32 = 1 << 24;
+const TXDESC_LAST_SEGMENT: u32 = 1 << 26;
+const TXDESC_FIRST_SEGMENT: u32 = 1 << 27;
+const TXDESC_OWN: u32 = 1 << 31;
+const TXDESC_QUEUE_SHIFT: u32 = 8;
+const TXDESC_QUEUE_BE: u32 = 0x00;
+const TXDESC_QUEUE_MGNT: u32 = 0x12;
+const TXDESC_USE_DRIVER_RATE: u32 = 1 << 8;
+const TXDESC_RETRY_LIMIT_ENABLE: u32 = 1 << 17;
+const TXDESC_RETRY_LIMIT_SHIFT: u32 = 18;
+const RETRY_LIMIT: u32 = 8;
+const RATE_1M: u32 = 0x00;
+const RATE_24M: u32 = 0x08;
+
+// Receive descriptor, and the alignment of each frame in a transfer
+const RX_DESC_SIZE: usize = 24;
+const RX_ALIGN: usize = 128;
+const RX_TRANSFER_SIZE: usize = 16384;
+const RXDESC_CRC32: u32 = 1 << 14;
+const RXDESC_ICV_ERROR: u32 = 1 << 15;
+const RXDESC_PHY_STATUS: u32 = 1 << 26;
+const FCS_LENGTH: usize = 4;
+// Frames held for the stack before the oldest are dropped
+const RX_QUEUE: usize = 64;
+// Rates up to 11 Mb/s are CCK, which reports its signal differently
+const RATE_11M: u32 = 0x03;
+
+const POLL_TIMEOUT_MS: u64 = 100;
+
+// The interface each adapter was registered as
+static ATTACHED: Mutex<Vec<(DeviceId, String)>> = Mutex::new(Vec::new());
+
+fn delay_us(us: u64) {
+    let until = now_ns() + us * 1000;
+    while now_ns() < until {
+        core::hint::spin_loop();
+    }
+}
+
+/// A setup table: address and value pairs, one to a line, in hex.
+/// Comments start with `//` or `#`.
+fn parse_table(text: &str) -> Vec<(u32, u32)> {
+    let hex = |word: &str| u32::from_str_radix(word.trim_start_matches("0x").trim_start_matches("0X"), 16).ok();
+    text.lines()
+        .map(|line| line.split("//").next().unwrap_or("").split('#').next().unwrap_or(""))
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            Some((hex(words.next()?)?, hex(words.next()?)?))
+        })
+        .collect()
+}
+
+// Baseband and radio tables use these addresses for a pause instead
+fn table_delay_us(address: u32) -> Option<u64> {
+    match address {
+        0xFE => Some(50_000),
+        0xFD => Some(5_000),
+        0xFC => Some(1_000),
+        0xFB => Some(50),
+        0xFA => Some(5),
+        0xF9 => Some(1),
+        _ => None,
+    }
+}
+
+/// Unpack the eFuse into its logical map. Each entry is a header, one or
+/// two bytes, giving an 8-byte section and which of its four words follow;
+/// words left out stay 0xFF.
+fn unpack_efuse(physical: &[u8]) -> Vec<u8> {
+    let mut logical = vec![0xFFu8; EFUSE_LOGICAL];
+    let mut at = 0;
+    while let Some(&header) = physical.get(at) {
+        if header == 0xFF {
+            break;
+        }
+        at += 1;
+        let (section, words) = if header & 0x1F == 0x0F {
+            let Some(&extended) = physical.get(at) else {
+                break;
+            };
+            at += 1;
+            if extended & 0x0F == 0x0F {
+                continue;
+            }
+            ((((header & 0xE0) >> 5) | ((extended & 0xF0) >> 1)) as usize, extended & 0x0F)
+        } else {
+            ((header >> 4) as usize, header & 0x0F)
+        };
+        for word in 0..4 {
+            // A set bit is a word left out
+            if words & (1 << word) != 0 {
+                continue;
+            }
+            let Some(data) = physical.get(at..at + 2) else {
+                return logical;
+            };
+            at += 2;
+            if let Some(slot) = logical.get_mut(section * 8 + word * 2..section * 8 + word * 2 + 2) {
+                slot.copy_from_slice(data);
+            }
+        }
+    }
+    logical
+}
+
+/// The frames in a receive transfer, each behind its descriptor and PHY
+/// status, with a good FCS, which is taken off
+fn parse_rx(mut data: &[u8]) -> Vec<RxFrame> {
+    let mut frames = Vec::new();
+    while data.len() >= RX_DESC_SIZE {
+        let word = |n: usize| u32::from_le_bytes(data[4 * n..4 * n + 4].try_into().unwrap());
+        let (dw0, dw3) = (word(0), word(3));
+        let length = (dw0 & 0x3FFF) as usize;
+        let phy_status = ((dw0 >> 16) & 0x0F) as usize * 8;
+        let shift = ((dw0 >> 24) & 0x03) as usize;
+        if length == 0 {
+            break;
+        }
+        let start = RX_DESC_SIZE + phy_status + shift;
+        let Some(frame) = data.get(start..start + length) else {
+            break;
+        };
+        if dw0 & (RXDESC_CRC32 | RXDESC_ICV_ERROR) == 0 && length > FCS_LENGTH {
+            let status = &data[RX_DESC_SIZE..RX_DESC_SIZE + phy_status];
+            let signal = match dw0 & RXDESC_PHY_STATUS != 0 && status.len() >= 6 {
+                true => signal(status, dw3 & 0x3F <= RATE_11M),
+                false => 0,
+            };
+            frames.push(RxFrame { data: frame[..length - FCS_LENGTH].to_vec(), signal });
+        }
+        let next = (start + length).next_multiple_of(RX_ALIGN);
+        data = data.get(next..).unwrap_or(&[]);
+    }
+    frames
+}
+
+// Signal strength in dBm from the PHY status: OFDM reports its power
+// outright, CCK the gains its LNA and VGA were set to
+fn signal(status: &[u8], cck: bool) -> i32 {
+    if !cck {
+        return ((status[4] >> 1) & 0x7F) as i32 - 110;
+    }
+    let report = status[5];
+    let (lna, vga) = ((report & 0xE0) >> 5, (report & 0x1F) as i32);
+    match lna {
+        7 if vga <= 27 => -100 + 2 * (27 - vga),
+        7 => -100,
+        6 => -48 + 2 * (2 - vga),
+        5 => -42 + 2 * (7 - vga),
+        4 => -36 + 2 * (7 - vga),
+        3 => -24 + 2 * (7 - vga),
+        2 => -12 + 2 * (5 - vga),
+        1 => 8 - 2 * vga,
+        _ => 14 - 2 * vga,
+    }
+}
+
+/// A transmit descriptor for `length` bytes of 802.11 frame
+fn tx_descriptor(length: usize, management: bool, group: bool) -> [u8; TX_DESC_SIZE] {
+    let mut words = [0u32; 8];
+    words[0] = length as u32 | (TX_DESC_SIZE as u32) << 16 | TXDESC_FIRST_SEGMENT | TXDESC_LAST_SEGMENT | TXDESC_OWN;
+    if group {
+        words[0] |= TXDESC_BROADMULTICAST;
+    }
+    let (queue, rate) = if management { (TXDESC_QUEUE_MGNT, RATE_1M) } else { (TXDESC_QUEUE_BE, RATE_24M) };
+    words[1] = queue << TXDESC_QUEUE_SHIFT;
+    words[4] = TXDESC_USE_DRIVER_RATE;
+    words[5] = rate | TXDESC_RETRY_LIMIT_ENABLE | RETRY_LIMIT << TXDESC_RETRY_LIMIT_SHIFT;
+    let mut descriptor = [0u8; TX_DESC_SIZE];
+    for (bytes, word) in descriptor.chunks_exact_mut(4).zip(words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    // The XOR of the descriptor's 16-bit words, its own field zero
+    let checksum = descriptor.chunks_exact(2).fold(0u16, |sum, pair| sum ^ u16::from_le_bytes([pair[0], pair[1]]));
+    descriptor[28..30].copy_from_slice(&checksum.to_le_bytes());
+    descriptor
+}
+
+fn read_firmware_file(path: &str) -> Result<Vec<u8>, ProbeError> {
+    crate::fs::vfs::VFS.lock().read_file_unchecked(path).map_err(|_| ProbeError::Failed("firmware or tables not found"))
+}
+
+pub struct Rtl8188eu {
+    address: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    max_packet: usize,
+    mac: MacAddress,
+    rx: VecDeque<RxFrame>,
+}
+
+impl Rtl8188eu {
+    fn request(&self, register: u16, length: usize, device_to_host: bool) -> DeviceRequest {
+        DeviceRequest {
+            request_type: if device_to_host { 0xC0 } else { 0x40 },
+            request: REALTEK_USB_REQUEST,
+            value: register,
+            index: 0,
+            length: length as u16,
+        }
+    }
+
+    fn read<const N: usize>(&self, register: u16) -> [u8; N] {
+        let mut data = [0u8; N];
+        if crate::usb::control_transfer(self.address, &self.request(register, N, true), Some(&mut data)).is_err() {
+            crate::serial_println!("rtl8188eu: cannot read register {:#06x}", register);
+        }
+        data
+    }
+
+    fn write(&self, register: u16, data: &[u8]) {
+        let mut data = data.to_vec();
+        let request = self.request(register, data.len(), false);
+        if crate::usb::control_transfer(self.address, &request, Some(&mut data)).is_err() {
+            crate::serial_println!("rtl8188eu: cannot write register {:#06x}", register);
+        }
+    }
+
+    fn read8(&self, register: u16) -> u8 {
+        self.read::<1>(register)[0]
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        u16::from_le_bytes(self.read(register))
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        u32::from_le_bytes(self.read(register))
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        self.write(register, &[value]);
+    }
+
+    fn write16(&self, register: u16, value: u16) {
+        self.write(register, &value.to_le_bytes());
+    }
+
+    fn write32(&self, register: u16, value: u32) {
+        self.write(register, &value.to_le_bytes());
+    }
+
+    // Wait up to POLL_TIMEOUT_MS for `done` to hold of a register
+    fn poll32(&self, register: u16, done: impl Fn(u32) -> bool) -> bool {
+        let deadline = now_ns() + POLL_TIMEOUT_MS * 1_000_000;
+        loop {
+            if done(self.read32(register)) {
+                return true;
+            }
+            if now_ns() >= deadline {
+                return false;
+            }
+            delay_us(10);
+        }
+    }
+
+    fn read_rf(&self, register: u8) -> u32 {
+        let mut hssi = self.read32(REG_FPGA0_XA_HSSI_PARM2);
+        let address = (hssi & !HSSI_READ_ADDRESS_MASK) | (register as u32) << HSSI_READ_ADDRESS_SHIFT | HSSI_READ_EDGE;
+        // The read is latched on the edge going up
+        hssi &= !HSSI_READ_EDGE;
+        self.write32(REG_FPGA0_XA_HSSI_PARM2, hssi);
+        delay_us(10);
+        self.write32(REG_FPGA0_XA_HSSI_PARM2, address);
+        delay_us(100);
+        self.write32(REG_FPGA0_XA_HSSI_PARM2, hssi | HSSI_READ_EDGE);
+        delay_us(10);
+        let readback = match self.read32(REG_FPGA0_XA_HSSI_PARM1) & HSSI_PI_MODE != 0 {
+            true => REG_HSPI_XA_READBACK,
+            false => REG_FPGA0_XA_LSSI_READBACK,
+        };
+        self.read32(readback) & RF_DATA_MASK
+    }
+
+    fn write_rf(&self, register: u8, value: u32) {
+        let data = ((register as u32) << 20 | (value & RF_DATA_MASK)) & 0x0FFF_FFFF;
+        self.write32(REG_FPGA0_XA_LSSI_PARM, data);
+        delay_us(1);
+    }
+
+    fn power_on(&self) -> Result<(), &'static str> {
+        if !self.poll32(REG_APS_FSMCO, |value| value & (1 << 17) != 0) {
+            return Err("power never came good");
+        }
+        // Reset the baseband, and let it go
+        let func = self.read8(REG_SYS_FUNC) & !((SYS_FUNC_BBRSTB | SYS_FUNC_BB_GLB_RSTN) as u8);
+        self.write8(REG_SYS_FUNC, func);
+        self.write8(REG_SYS_FUNC, func | (SYS_FUNC_BBRSTB | SYS_FUNC_BB_GLB_RSTN) as u8);
+        self.write8(REG_AFE_XTAL_CTRL + 2, self.read8(REG_AFE_XTAL_CTRL + 2) | 0x80);
+        // Out of hardware power-down and suspend, then ask for power and
+        // wait for the chip to clear the request
+        let fsmco = self.read8(REG_APS_FSMCO + 1) & !0x98;
+        self.write8(REG_APS_FSMCO + 1, fsmco | 0x01);
+        if !self.poll32(REG_APS_FSMCO, |value| value & (1 << 8) == 0) {
+            return Err("the chip did not power up");
+        }
+        self.write8(REG_LPLDO_CTRL, self.read8(REG_LPLDO_CTRL) & !0x10);
+        self.write16(REG_CR, self.read16(REG_CR) | CR_ENABLE);
+        Ok(())
+    }
+
+    fn read_efuse(&self) -> Result<Vec<u8>, &'static str> {
+        if self.read16(REG_9346CR) & (EEPROM_BOOT | EEPROM_ENABLE) != EEPROM_ENABLE {
+            return Err("eFuse not loaded");
+        }
+        self.write16(REG_SYS_ISO_CTRL, self.read16(REG_SYS_ISO_CTRL) | SYS_ISO_PWC_EV12V);
+        self.write16(REG_SYS_FUNC, self.read16(REG_SYS_FUNC) | SYS_FUNC_ELDR);
+        self.write16(REG_SYS_CLKR, self.read16(REG_SYS_CLKR) | SYS_CLK_LOADER_ENABLE | SYS_CLK_ANA8M);
+
+        let mut physical = vec![0xFFu8; EFUSE_PHYSICAL];
+        for (offset, byte) in physical.iter_mut().enumerate() {
+            self.write8(REG_EFUSE_CTRL + 1, offset as u8);
+            self.write8(REG_EFUSE_CTRL + 2, (self.read8(REG_EFUSE_CTRL + 2) & 0xFC) | (offset >> 8) as u8 & 0x03);
+            self.write8(REG_EFUSE_CTRL + 3, self.read8(REG_EFUSE_CTRL + 3) & 0x7F);
+            if !self.poll32(REG_EFUSE_CTRL, |value| value & EFUSE_READ_DONE != 0) {
+                return Err("eFuse read timed out");
+            }
+            *byte = self.read8(REG_EFUSE_CTRL);
+            if offset == 0 && *byte == 0xFF {
+                return Err("eFuse is empty");
+            }
+        }
+        Ok(unpack_efuse(&physical))
+    }
+
+    fn load_firmware(&self, image: &[u8]) -> Result<(), &'static str> {
+        let signature = u16::from_le_bytes([image.first().copied().unwrap_or(0), image.get(1).copied().unwrap_or(0)]);
+        let code = match signature & 0xFFF0 == FW_SIGNATURE {
+            true => image.get(FW_HEADER_SIZE..).ok_or("firmware too short")?,
+            false => return Err("not RTL8188E firmware"),
+        };
+
+        // Firmware left running from before is stopped first
+        if self.read32(REG_MCU_FW_DL) & MCU_FW_RAM_SEL != 0 {
+            self.write8(REG_MCU_FW_DL, 0);
+            self.write16(REG_SYS_FUNC, self.read16(REG_SYS_FUNC) & !SYS_FUNC_CPU_ENABLE);
+        }
+        self.write16(REG_SYS_FUNC, self.read16(REG_SYS_FUNC) | SYS_FUNC_CPU_ENABLE);
+        let download = (self.read32(REG_MCU_FW_DL) | MCU_FW_DL_ENABLE | MCU_FW_DL_CSUM_REPORT) & !MCU_8051_RESET;
+        self.write32(REG_MCU_FW_DL, download);
+
+        // 4 KB at a time into the window, the page chosen in the third byte
+        for (page, chunk) in code.chunks(FW_PAGE_SIZE).enumerate() {
+            self.write8(REG_MCU_FW_DL + 2, (self.read8(REG_MCU_FW_DL + 2) & 0xF8) | page as u8);
+            for (block, data) in chunk.chunks(WRITE_BLOCK).enumerate() {
+                self.write(REG_FW_START + (block * WRITE_BLOCK) as u16, data);
+            }
+        }
+        self.write16(REG_MCU_FW_DL, self.read16(REG_MCU_FW_DL) & !(MCU_FW_DL_ENABLE as u16));
+
+        if !self.poll32(REG_MCU_FW_DL, |value| value & MCU_FW_DL_CSUM_REPORT != 0) {
+            return Err("firmware checksum failed");
+        }
+        let ready = (self.read32(REG_MCU_FW_DL) | MCU_FW_DL_READY) & !MCU_WINT_INIT_READY;
+        self.write32(REG_MCU_FW_DL, ready);
+        // The 8051 has to be reset for the firmware to start
+        let func = self.read16(REG_SYS_FUNC);
+        self.write16(REG_SYS_FUNC, func & !SYS_FUNC_CPU_ENABLE);
+        self.write16(REG_SYS_FUNC, func | SYS_FUNC_CPU_ENABLE);
+        if !self.poll32(REG_MCU_FW_DL, |value| value & MCU_WINT_INIT_READY != 0) {
+            return Err("firmware did not start");
+        }
+        Ok(())
+    }
+
+    fn write_llt(&self, address: u32, next: u32) -> Result<(), &'static str> {
+        self.write32(REG_LLT_INIT, LLT_WRITE | address << 8 | next);
+        match self.poll32(REG_LLT_INIT, |value| value & LLT_BUSY == 0) {
+            true => Ok(()),
+            false => Err("link list table write timed out"),
+        }
+    }
+
+    // Chain the transmit pages, and the rest into a ring for the beacon
+    // and receive side
+    fn init_queues(&self) -> Result<(), &'static str> {
+        let public = TX_PAGES - HIGH_PAGES - LOW_PAGES - NORMAL_PAGES;
+        self.write8(REG_RQPN_NPQ, NORMAL_PAGES as u8);
+        self.write32(REG_RQPN, RQPN_LOAD | public << 16 | LOW_PAGES << 8 | HIGH_PAGES);
+        self.write8(REG_TRXFF_BNDY, (TX_PAGES + 1) as u8);
+        self.write16(REG_TRXFF_BNDY + 2, RX_FIFO_BOUNDARY);
+        for page in 0..TX_PAGES {
+            self.write_llt(page, page + 1)?;
+        }
+        self.write_llt(TX_PAGES, 0xFF)?;
+        for page in TX_PAGES + 1..LAST_LLT_ENTRY {
+            self.write_llt(page, page + 1)?;
+        }
+        self.write_llt(LAST_LLT_ENTRY, TX_PAGES + 1)?;
+        self.write16(REG_TRXDMA_CTRL, (self.read16(REG_TRXDMA_CTRL) & 0x0003) | TRXDMA_ALL_HIGH);
+        Ok(())
+    }
+
+    fn write_bb_table(&self, table: &[(u32, u32)]) {
+        for &(address, value) in table {
+            match table_delay_us(address) {
+                Some(us) => delay_us(us),
+                None => self.write32(address as u16, value),
+            }
+        }
+    }
+
+    fn init_phy(&self, mac: &[(u32, u32)], bb: &[(u32, u32)], agc: &[(u32, u32)], rf: &[(u32, u32)]) {
+        for &(address, value) in mac {
+            self.write8(address as u16, value as u8);
+        }
+        self.write16(REG_SYS_FUNC, self.read16(REG_SYS_FUNC) | SYS_FUNC_BB_GLB_RSTN | SYS_FUNC_BBRSTB);
+        self.write8(REG_RF_CTRL, RF_ENABLE);
+        self.write8(REG_SYS_FUNC, (SYS_FUNC_USBA | SYS_FUNC_USBD | SYS_FUNC_BB_GLB_RSTN | SYS_FUNC_BBRSTB) as u8);
+        self.write_bb_table(bb);
+        self.write_bb_table(agc);
+        for &(address, value) in rf {
+            match table_delay_us(address) {
+                Some(us) => delay_us(us),
+                None => self.write_rf(address as u8, value),
+            }
+        }
+
+        // 20 MHz wide
+        self.write8(REG_BWOPMODE, self.read8(REG_BWOPMODE) | BW_OPMODE_20MHZ);
+        self.write32(REG_FPGA0_RF_MODE, self.read32(REG_FPGA0_RF_MODE) & !1);
+        self.write32(REG_FPGA1_RF_MODE, self.read32(REG_FPGA1_RF_MODE) & !1);
+        self.write_rf(RF_MODE_AG, self.read_rf(RF_MODE_AG) | RF_BW_20MHZ);
+    }
+
+    fn init_mac(&self) {
+        self.write(REG_MACID, self.mac.as_bytes());
+        self.write8(REG_MSR, (self.read8(REG_MSR) & !0x03) | MSR_STATION);
+        let rcr = RCR_ACCEPT_PHYS_MATCH
+            | RCR_ACCEPT_MCAST
+            | RCR_ACCEPT_BCAST
+            | RCR_ACCEPT_DATA
+            | RCR_ACCEPT_MGMT
+            | RCR_APPEND_PHYSTAT;
+        self.write32(REG_RCR, rcr);
+        // Every management and data subtype through
+        self.write16(REG_RXFLTMAP0, 0xFFFF);
+        self.write16(REG_RXFLTMAP2, 0xFFFF);
+    }
+
+    fn open(device: &crate::usb::UsbDevice) -> Result<Self, ProbeError> {
+        let (bulk_in, bulk_out) = device
+            .interfaces
+            .iter()
+            .find_map(|interface| Some((interface.bulk_in()?.clone(), interface.bulk_out()?.clone())))
+            .ok_or(ProbeError::NoDevice)?;
+        let firmware = read_firmware_file(FIRMWARE)?;
+        let tables = [MAC_TABLE, BB_TABLE, AGC_TABLE, RF_TABLE]
+            .map(|path| read_firmware_file(path).map(|text| parse_table(&String::from_utf8_lossy(&text))));
+        let [mac_table, bb_table, agc_table, rf_table] = tables;
+        let (mac_table, bb_table, agc_table, rf_table) = (mac_table?, bb_table?, agc_table?, rf_table?);
+
+        let mut radio = Rtl8188eu {
+            address: device.address,
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            max_packet: bulk_out.max_packet_size.max(1) as usize,
+            mac: MacAddress::ZERO,
+            rx: VecDeque::new(),
+        };
+        radio.power_on().map_err(ProbeError::Failed)?;
+        let efuse = radio.read_efuse().map_err(ProbeError::Failed)?;
+        radio.mac = MacAddress::from_bytes(&efuse[EFUSE_MAC..EFUSE_MAC + 6]).ok_or(ProbeError::Failed("no MAC address"))?;
+        radio.load_firmware(&firmware).map_err(ProbeError::Failed)?;
+        radio.init_queues().map_err(ProbeError::Failed)?;
+        radio.init_phy(&mac_table, &bb_table, &agc_table, &rf_table);
+        radio.init_mac();
+        Ok(radio)
+    }
+}
+
+impl Radio for Rtl8188eu {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn set_channel(&mut self, channel: u8) -> Result<(), &'static str> {
+        if !(1..=13).contains(&channel) {
+            return Err("no such channel");
+        }
+        self.write_rf(RF_MODE_AG, (self.read_rf(RF_MODE_AG) & !RF_CHANNEL_MASK) | channel as u32);
+        Ok(())
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        let management = frame.first().is_some_and(|fc0| fc0 & 0x0C == 0);
+        let group = frame.get(4).is_some_and(|addr1| addr1 & 1 != 0);
+        let mut data = Vec::with_capacity(TX_DESC_SIZE + frame.len());
+        data.extend_from_slice(&tx_descriptor(frame.len(), management, group));
+        data.extend_from_slice(frame);
+        crate::usb::bulk_transfer(self.address, self.bulk_out, &mut data, true)?;
+        // A transfer that fills its last packet is ended with an empty one
+        if data.len() % self.max_packet == 0 {
+            crate::usb::bulk_transfer(self.address, self.bulk_out, &mut [], true)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<RxFrame> {
+        if self.rx.is_empty() {
+            let mut buffer = vec![0u8; RX_TRANSFER_SIZE];
+            let count = crate::usb::bulk_transfer(self.address, self.bulk_in, &mut buffer, false).ok()?;
+            for frame in parse_rx(&buffer[..count]) {
+                if self.rx.len() == RX_QUEUE {
+                    self.rx.pop_front();
+                }
+                self.rx.push_back(frame);
+            }
+        }
+        self.rx.pop_front()
+    }
+}
+
+pub static USB_DRIVER: Rtl8188euDriver = Rtl8188euDriver;
+
+pub struct Rtl8188euDriver;
+
+impl Driver for Rtl8188euDriver {
+    fn name(&self) -> &'static str {
+        "rtl8188eu"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // Realtek's own RTL8188EUS and RTL8188ETV
+            Match::UsbId { vendor: 0x0BDA, product: 0x8179 },
+            Match::UsbId { vendor: 0x0BDA, product: 0x0179 },
+            // TP-Link TL-WN722N v2 and v3, and TL-WN727N v5
+            Match::UsbId { vendor: 0x2357, product: 0x010C },
+            Match::UsbId { vendor: 0x2357, product: 0x0111 },
+            // D-Link DWA-123 D1
+            Match::UsbId { vendor: 0x2001, product: 0x3310 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let radio = Rtl8188eu::open(&usb)?;
+        let mac = radio.mac;
+        let description = format!("rtl8188eu at USB address {}", address);
+        let name = crate::net::wireless::attach(Box::new(SoftMac::new(radio)), description);
+        crate::serial_println!("rtl8188eu: {} at USB address {}, {}", name, address, mac);
+        ATTACHED.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        ATTACHED.lock().retain(|(id, name)| {
+            if *id == device.id {
+                crate::net::wireless::detach(name);
+            }
+            *id != device.id
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_table() {
+        let text = "// MAC_REG\n0x026 0x41\n0x027\t0x35 // trailing\n# skipped\n\n0xFE 0x0\n";
+        assert_eq!(parse_table(text), vec![(0x026, 0x41), (0x027, 0x35), (0xFE, 0)]);
+        assert_eq!(table_delay_us(0xFE), Some(50_000));
+        assert_eq!(table_delay_us(0x800), None);
+    }
+
+    #[test]
+    fn test_unpack_efuse() {
+        // Section 1, words 0 and 2 present; then an extended header for
+        // section 0x1B (0xD8 / 8), word 0 present
+        let physical = [0x1A, 0x11, 0x22, 0x33, 0x44, 0x6F, 0x3E, 0xAA, 0xBB, 0xFF];
+        let logical = unpack_efuse(&physical);
+        assert_eq!(&logical[8..10], &[0x11, 0x22]);
+        assert_eq!(&logical[10..12], &[0xFF, 0xFF]);
+        assert_eq!(&logical[12..14], &[0x33, 0x44]);
+        assert_eq!(&logical[0xD8..0xDA], &[0xAA, 0xBB]);
+        assert_eq!(unpack_efuse(&[0x1A, 0x11]).len(), EFUSE_LOGICAL);
+    }
+
+    #[test]
+    fn test_parse_rx() {
+        let mut transfer = Vec::new();
+        let frame = [0x80u8; 30];
+        let dw0 = (frame.len() as u32) | 1 << 16 | RXDESC_PHY_STATUS;
+        let mut descriptor = [0u8; RX_DESC_SIZE];
+        descriptor[..4].copy_from_slice(&dw0.to_le_bytes());
+        // OFDM at 6 Mb/s, -50 dBm
+        descriptor[12..16].copy_from_slice(&0x04u32.to_le_bytes());
+        transfer.extend_from_slice(&descriptor);
+        transfer.extend_from_slice(&[0, 0, 0, 0, 120, 0, 0, 0]);
+        transfer.extend_from_slice(&frame);
+        transfer.resize(RX_ALIGN, 0);
+        // A second frame with a bad FCS
+        let mut bad = descriptor;
+        bad[..4].copy_from_slice(&(dw0 | RXDESC_CRC32).to_le_bytes());
+        transfer.extend_from_slice(&bad);
+        transfer.extend_from_slice(&[0; 8]);
+        transfer.extend_from_slice(&frame);
+
+        let frames = parse_rx(&transfer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data.len(), frame.len() - FCS_LENGTH);
+        assert_eq!(frames[0].signal, -50);
+        assert!(parse_rx(&descriptor[..10]).is_empty());
+    }
+
+    #[test]
+    fn test_tx_descriptor() {
+        let descriptor = tx_descriptor(100, true, true);
+        let dw0 = u32::from_le_bytes(descriptor[..4].try_into().unwrap());
+        assert_eq!(dw0 & 0xFFFF, 100);
+        assert_eq!((dw0 >> 16) & 0xFF, TX_DESC_SIZE as u32);
+        assert_ne!(dw0 & TXDESC_BROADMULTICAST, 0);
+        let dw1 = u32::from_le_bytes(descriptor[4..8].try_into().unwrap());
+        assert_eq!((dw1 >> TXDESC_QUEUE_SHIFT) & 0x1F, TXDESC_QUEUE_MGNT);
+        // XORing in the checksum brings the whole descriptor to zero
+        let sum = descriptor.chunks_exact(2).fold(0u16, |sum, pair| sum ^ u16::from_le_bytes([pair[0], pair[1]]));
+        assert_eq!(sum, 0);
+    }
+}
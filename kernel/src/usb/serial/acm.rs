@@ -0,0 +1,119 @@
+//! CDC-ACM: modems, and the serial ports of most microcontroller boards
+//!
+//! The line is set with SET_LINE_CODING, always 8N1, and DTR and RTS with
+//! SET_CONTROL_LINE_STATE. Carrier, DSR and ring come back as SERIAL_STATE
+//! notifications on the interrupt endpoint. ACM has no request for flow
+//! control, so asking for it fails.
+//!
+//! A communication interface of protocol 0xFF is RNDIS, not a modem, and
+//! is left to `usbnet::rndis`.
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::serial::tty::{TIOCM_CAR, TIOCM_DSR, TIOCM_DTR, TIOCM_RNG, TIOCM_RTS};
+use crate::usb::cdc::{self, Function};
+use crate::usb::{USB_CLASS_CDC, USB_CLASS_MISC};
+
+use super::{Chip, Link};
+
+// Vendor-specific protocol: RNDIS on an ACM interface
+const PROTOCOL_VENDOR: u8 = 0xFF;
+
+/// Set the speed and DTR/RTS
+pub fn configure(link: &Link) -> Result<(), &'static str> {
+    let Chip::Acm { interface } = link.chip else {
+        return Err("not a CDC-ACM port");
+    };
+    if link.flow_control {
+        return Err("CDC-ACM has no flow control setting");
+    }
+    // Speed, one stop bit, no parity, eight data bits
+    let mut coding = [0u8; 7];
+    coding[..4].copy_from_slice(&link.baud.to_le_bytes());
+    coding[6] = 8;
+    let request = cdc::class_request(cdc::SET_LINE_CODING, 0, interface, coding.len() as u16, false);
+    crate::usb::control_transfer(link.address, &request, Some(&mut coding))?;
+
+    let mut lines = 0;
+    if link.lines & TIOCM_DTR != 0 {
+        lines |= cdc::LINE_DTR;
+    }
+    if link.lines & TIOCM_RTS != 0 {
+        lines |= cdc::LINE_RTS;
+    }
+    let request = cdc::class_request(cdc::SET_CONTROL_LINE_STATE, lines, interface, 0, false);
+    crate::usb::control_transfer(link.address, &request, None).map(|_| ())
+}
+
+/// The TIOCM_* bits a SERIAL_STATE notification reports, if that is what
+/// `notification` is
+pub fn serial_state(notification: &[u8]) -> Option<u32> {
+    if notification.len() < 10 || notification[1] != cdc::NOTIFY_SERIAL_STATE {
+        return None;
+    }
+    let state = u16::from_le_bytes([notification[8], notification[9]]);
+    let bits = [
+        (cdc::SERIAL_STATE_DCD, TIOCM_CAR),
+        (cdc::SERIAL_STATE_DSR, TIOCM_DSR),
+        (cdc::SERIAL_STATE_RING, TIOCM_RNG),
+    ];
+    Some(bits.iter().filter(|(bit, _)| state & bit != 0).fold(0, |lines, (_, tiocm)| lines | tiocm))
+}
+
+pub static USB_DRIVER: AcmDriver = AcmDriver;
+
+pub struct AcmDriver;
+
+impl Driver for AcmDriver {
+    fn name(&self) -> &'static str {
+        "cdc_acm"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // CDC at the device level, or taken from its first interface
+            Match::UsbClass { class: USB_CLASS_CDC, subclass: None, protocol: None },
+            // Composite, with interface association descriptors
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(2), protocol: Some(1) },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let control = usb
+            .interfaces
+            .iter()
+            .find(|interface| {
+                interface.class == USB_CLASS_CDC
+                    && interface.subclass == cdc::SUBCLASS_ACM
+                    && interface.protocol != PROTOCOL_VENDOR
+            })
+            .ok_or(ProbeError::NoDevice)?;
+        let function = Function::with_control(&usb, control).ok_or(ProbeError::Failed("no data interface"))?;
+        let data = function.data_setting(&usb).ok_or(ProbeError::Failed("no bulk endpoints"))?;
+        let (bulk_in, bulk_out) = (data.bulk_in().unwrap(), data.bulk_out().unwrap());
+        let link = Link {
+            device: device.id,
+            address,
+            chip: Chip::Acm { interface: control.number },
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            interrupt_in: control.interrupt_in().map(|endpoint| endpoint.address),
+            max_packet: bulk_in.max_packet_size as usize,
+            baud: 0,
+            flow_control: false,
+            lines: 0,
+        };
+        super::attach(link).map(|_| ()).map_err(ProbeError::Failed)
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
@@ -0,0 +1,162 @@
+//! Silicon Labs CP210x USB serial chips
+//!
+//! Vendor requests to the interface: IFC_ENABLE turns the UART on,
+//! SET_BAUDRATE takes the speed in bits per second, SET_FLOW the
+//! handshake, and SET_MHS and GET_MDMSTS write and read the modem lines.
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::serial::tty::{TIOCM_CAR, TIOCM_CTS, TIOCM_DSR, TIOCM_DTR, TIOCM_RNG, TIOCM_RTS};
+use crate::usb::DeviceRequest;
+
+use super::{Chip, Link};
+
+const VENDOR_SILABS: u16 = 0x10C4;
+
+// Requests
+const IFC_ENABLE: u8 = 0x00;
+const SET_LINE_CTL: u8 = 0x03;
+const SET_MHS: u8 = 0x07;
+const GET_MDMSTS: u8 = 0x08;
+const SET_FLOW: u8 = 0x13;
+const SET_BAUDRATE: u8 = 0x1E;
+
+const UART_ENABLE: u16 = 0x0001;
+// SET_LINE_CTL: one stop bit, no parity, eight data bits
+const LINE_8N1: u16 = 0x0800;
+// SET_MHS: the low byte sets the lines the high byte selects
+const MHS_DTR: u16 = 0x0101;
+const MHS_RTS: u16 = 0x0202;
+
+// SET_FLOW: DTR held on, then CTS handshaking; RTS held on, or RTS
+// following the receive buffer
+const HANDSHAKE_DTR_ACTIVE: u32 = 0x01;
+const HANDSHAKE_CTS: u32 = 0x08;
+const REPLACE_RTS_ACTIVE: u32 = 0x40;
+const REPLACE_RTS_FLOW: u32 = 0x80;
+// Bytes of room and fill at which RTS goes up and down
+const XON_LIMIT: u32 = 128;
+const XOFF_LIMIT: u32 = 128;
+
+// GET_MDMSTS
+const STATUS_CTS: u8 = 0x10;
+const STATUS_DSR: u8 = 0x20;
+const STATUS_RI: u8 = 0x40;
+const STATUS_DCD: u8 = 0x80;
+
+// The chips' top speed
+const MAX_BAUD: u32 = 3_000_000;
+
+fn request(request: u8, value: u16, interface: u8, length: u16, device_to_host: bool) -> DeviceRequest {
+    DeviceRequest {
+        // Vendor, interface
+        request_type: if device_to_host { 0xC1 } else { 0x41 },
+        request,
+        value,
+        index: interface as u16,
+        length,
+    }
+}
+
+fn send(address: u8, code: u8, value: u16, interface: u8, data: Option<&mut [u8]>) -> Result<(), &'static str> {
+    let length = data.as_ref().map_or(0, |data| data.len() as u16);
+    crate::usb::control_transfer(address, &request(code, value, interface, length, false), data).map(|_| ())
+}
+
+/// Set the speed, 8N1, flow control and DTR/RTS
+pub fn configure(link: &Link) -> Result<(), &'static str> {
+    let Chip::Cp210x { interface } = link.chip else {
+        return Err("not a CP210x port");
+    };
+    if link.baud == 0 || link.baud > MAX_BAUD {
+        return Err("speed out of range");
+    }
+    send(link.address, SET_BAUDRATE, 0, interface, Some(&mut link.baud.to_le_bytes()))?;
+    send(link.address, SET_LINE_CTL, LINE_8N1, interface, None)?;
+
+    let (handshake, replace) = if link.flow_control {
+        (HANDSHAKE_DTR_ACTIVE | HANDSHAKE_CTS, REPLACE_RTS_FLOW)
+    } else {
+        (HANDSHAKE_DTR_ACTIVE, REPLACE_RTS_ACTIVE)
+    };
+    let mut flow = [0u8; 16];
+    for (field, value) in flow.chunks_mut(4).zip([handshake, replace, XON_LIMIT, XOFF_LIMIT]) {
+        field.copy_from_slice(&value.to_le_bytes());
+    }
+    send(link.address, SET_FLOW, 0, interface, Some(&mut flow))?;
+
+    let mut lines = MHS_DTR & 0xFF00 | MHS_RTS & 0xFF00;
+    if link.lines & TIOCM_DTR != 0 {
+        lines |= MHS_DTR & 0xFF;
+    }
+    if link.lines & TIOCM_RTS != 0 {
+        lines |= MHS_RTS & 0xFF;
+    }
+    send(link.address, SET_MHS, lines, interface, None)
+}
+
+/// CTS, DSR, ring and carrier, as TIOCM_* bits
+pub fn modem_status(link: &Link) -> Result<u32, &'static str> {
+    let Chip::Cp210x { interface } = link.chip else {
+        return Err("not a CP210x port");
+    };
+    let mut status = [0u8; 1];
+    let request = request(GET_MDMSTS, 0, interface, status.len() as u16, true);
+    crate::usb::control_transfer(link.address, &request, Some(&mut status))?;
+    Ok([(STATUS_CTS, TIOCM_CTS), (STATUS_DSR, TIOCM_DSR), (STATUS_RI, TIOCM_RNG), (STATUS_DCD, TIOCM_CAR)]
+        .iter()
+        .filter(|(bit, _)| status[0] & bit != 0)
+        .fold(0, |lines, (_, tiocm)| lines | tiocm))
+}
+
+pub static USB_DRIVER: Cp210xDriver = Cp210xDriver;
+
+pub struct Cp210xDriver;
+
+impl Driver for Cp210xDriver {
+    fn name(&self) -> &'static str {
+        "cp210x"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbId { vendor: VENDOR_SILABS, product: 0xEA60 },
+            Match::UsbId { vendor: VENDOR_SILABS, product: 0xEA70 },
+            Match::UsbId { vendor: VENDOR_SILABS, product: 0xEA71 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let interface = usb
+            .interfaces
+            .iter()
+            .find(|interface| interface.bulk_in().is_some() && interface.bulk_out().is_some())
+            .ok_or(ProbeError::Failed("no bulk endpoints"))?;
+        send(address, IFC_ENABLE, UART_ENABLE, interface.number, None).map_err(ProbeError::Failed)?;
+        let (bulk_in, bulk_out) = (interface.bulk_in().unwrap(), interface.bulk_out().unwrap());
+        let link = Link {
+            device: device.id,
+            address,
+            chip: Chip::Cp210x { interface: interface.number },
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            interrupt_in: None,
+            max_packet: bulk_in.max_packet_size as usize,
+            baud: 0,
+            flow_control: false,
+            lines: 0,
+        };
+        super::attach(link).map(|_| ()).map_err(ProbeError::Failed)
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
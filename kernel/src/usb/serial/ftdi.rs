@@ -0,0 +1,186 @@
+//! FTDI USB serial chips: FT232R and BM, FT2232, FT4232, FT232H, FT-X
+//!
+//! Everything is a vendor request on the default pipe. The speed is a
+//! divisor of 3 MHz with a fraction in eighths, which every one of these
+//! chips takes; the high-speed ones' 120 MHz clock is not used, so the top
+//! speed is 3 Mbaud. Each packet the chip sends starts with two bytes of
+//! modem and line status before the data. Only the first port of the
+//! multi-port chips is attached.
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::serial::tty::{TIOCM_CAR, TIOCM_CTS, TIOCM_DSR, TIOCM_DTR, TIOCM_RNG, TIOCM_RTS};
+use crate::usb::DeviceRequest;
+
+use super::{Chip, Link};
+
+const VENDOR_FTDI: u16 = 0x0403;
+// FT2232 and FT4232: more than one port, each an interface
+const MULTI_PORT: [u16; 2] = [0x6010, 0x6011];
+
+// Requests
+const SIO_RESET: u8 = 0x00;
+const SIO_MODEM_CTRL: u8 = 0x01;
+const SIO_SET_FLOW_CTRL: u8 = 0x02;
+const SIO_SET_BAUD_RATE: u8 = 0x03;
+const SIO_SET_DATA: u8 = 0x04;
+const SIO_GET_MODEM_STATUS: u8 = 0x05;
+
+// SIO_MODEM_CTRL: the low byte sets the lines the high byte selects
+const MODEM_DTR: u16 = 0x0101;
+const MODEM_RTS: u16 = 0x0202;
+// SIO_SET_FLOW_CTRL, in the high byte of the index
+const FLOW_RTS_CTS: u16 = 0x0100;
+// SIO_SET_DATA: eight data bits, no parity, one stop bit
+const DATA_8N1: u16 = 8;
+
+// Status byte, in SIO_GET_MODEM_STATUS and at the start of each packet
+const STATUS_CTS: u8 = 0x10;
+const STATUS_DSR: u8 = 0x20;
+const STATUS_RI: u8 = 0x40;
+const STATUS_RLSD: u8 = 0x80;
+
+const BASE_CLOCK: u32 = 3_000_000;
+// Eighths of the divisor, as the chip encodes them
+const FRACTION_CODE: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+
+fn request(request: u8, value: u16, index: u16, length: u16, device_to_host: bool) -> DeviceRequest {
+    DeviceRequest {
+        // Vendor, device
+        request_type: if device_to_host { 0xC0 } else { 0x40 },
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+fn send(address: u8, code: u8, value: u16, index: u16) -> Result<(), &'static str> {
+    crate::usb::control_transfer(address, &request(code, value, index, 0, false), None).map(|_| ())
+}
+
+// The value and index that set `baud`
+fn divisor(baud: u32, port: u16) -> Result<(u16, u16), &'static str> {
+    if baud == 0 || baud > BASE_CLOCK {
+        return Err("speed out of range");
+    }
+    let eighths = (BASE_CLOCK * 8 + baud / 2) / baud;
+    // Fourteen bits of whole divisor
+    if eighths >> 3 >= 1 << 14 {
+        return Err("speed out of range");
+    }
+    // 3 and 2 Mbaud have codes of their own
+    let divisor = match eighths >> 3 | FRACTION_CODE[(eighths & 7) as usize] << 14 {
+        1 => 0,
+        0x4001 => 1,
+        divisor => divisor,
+    };
+    let index = (divisor >> 16) as u16;
+    let index = if port != 0 { index << 8 | port } else { index };
+    Ok((divisor as u16, index))
+}
+
+/// Set the speed, 8N1, flow control and DTR/RTS
+pub fn configure(link: &Link) -> Result<(), &'static str> {
+    let Chip::Ftdi { port } = link.chip else {
+        return Err("not an FTDI port");
+    };
+    let (value, index) = divisor(link.baud, port)?;
+    send(link.address, SIO_SET_BAUD_RATE, value, index)?;
+    send(link.address, SIO_SET_DATA, DATA_8N1, port)?;
+    let flow = if link.flow_control { FLOW_RTS_CTS } else { 0 };
+    send(link.address, SIO_SET_FLOW_CTRL, 0, flow | port)?;
+    let mut lines = MODEM_DTR & 0xFF00 | MODEM_RTS & 0xFF00;
+    if link.lines & TIOCM_DTR != 0 {
+        lines |= MODEM_DTR & 0xFF;
+    }
+    if link.lines & TIOCM_RTS != 0 {
+        lines |= MODEM_RTS & 0xFF;
+    }
+    send(link.address, SIO_MODEM_CTRL, lines, port)
+}
+
+fn status_bits(status: u8) -> u32 {
+    [(STATUS_CTS, TIOCM_CTS), (STATUS_DSR, TIOCM_DSR), (STATUS_RI, TIOCM_RNG), (STATUS_RLSD, TIOCM_CAR)]
+        .iter()
+        .filter(|(bit, _)| status & bit != 0)
+        .fold(0, |lines, (_, tiocm)| lines | tiocm)
+}
+
+/// CTS, DSR, ring and carrier, as TIOCM_* bits
+pub fn modem_status(link: &Link) -> Result<u32, &'static str> {
+    let Chip::Ftdi { port } = link.chip else {
+        return Err("not an FTDI port");
+    };
+    let mut status = [0u8; 2];
+    let request = request(SIO_GET_MODEM_STATUS, 0, port, status.len() as u16, true);
+    crate::usb::control_transfer(link.address, &request, Some(&mut status))?;
+    Ok(status_bits(status[0]))
+}
+
+/// Copy the data out of what the chip sent, dropping the two status bytes
+/// that start each packet, and return how much there was
+pub fn strip_status(received: &[u8], max_packet: usize, data: &mut [u8]) -> usize {
+    let mut count = 0;
+    for packet in received.chunks(max_packet.max(3)) {
+        let payload = packet.get(2..).unwrap_or(&[]);
+        data[count..count + payload.len()].copy_from_slice(payload);
+        count += payload.len();
+    }
+    count
+}
+
+pub static USB_DRIVER: FtdiDriver = FtdiDriver;
+
+pub struct FtdiDriver;
+
+impl Driver for FtdiDriver {
+    fn name(&self) -> &'static str {
+        "ftdi_sio"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbId { vendor: VENDOR_FTDI, product: 0x6001 },
+            Match::UsbId { vendor: VENDOR_FTDI, product: 0x6010 },
+            Match::UsbId { vendor: VENDOR_FTDI, product: 0x6011 },
+            Match::UsbId { vendor: VENDOR_FTDI, product: 0x6014 },
+            Match::UsbId { vendor: VENDOR_FTDI, product: 0x6015 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, product, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let interface = usb
+            .interfaces
+            .iter()
+            .find(|interface| interface.bulk_in().is_some() && interface.bulk_out().is_some())
+            .ok_or(ProbeError::Failed("no bulk endpoints"))?;
+        let port = if MULTI_PORT.contains(&product) { interface.number as u16 + 1 } else { 0 };
+        send(address, SIO_RESET, 0, port).map_err(ProbeError::Failed)?;
+        let (bulk_in, bulk_out) = (interface.bulk_in().unwrap(), interface.bulk_out().unwrap());
+        let link = Link {
+            device: device.id,
+            address,
+            chip: Chip::Ftdi { port },
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            interrupt_in: None,
+            max_packet: bulk_in.max_packet_size as usize,
+            baud: 0,
+            flow_control: false,
+            lines: 0,
+        };
+        super::attach(link).map(|_| ()).map_err(ProbeError::Failed)
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
@@ -0,0 +1,421 @@
+//! USB serial adapters
+//!
+//! Adapters that follow CDC-ACM are `/dev/ttyACM*`; FTDI and Silicon Labs
+//! CP210x chips, which each speak their own vendor protocol, are
+//! `/dev/ttyUSB*`. Either way the port is a pair of rings like a UART's,
+//! filled and drained over the bulk endpoints by `poll`, which the main
+//! loop calls. Each chip's driver only sets the line up and reads the
+//! modem status; see `acm`, `ftdi` and `cp210x`.
+//!
+//! A port keeps its number while the adapter is plugged in. Once it is
+//! unplugged, the number is free for the next adapter only after whoever
+//! had the port open has closed it, and until then their calls fail.
+
+pub mod acm;
+pub mod cp210x;
+pub mod ftdi;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::driver::DeviceId;
+use crate::serial::ring::Ring;
+use crate::serial::tty::{SerialConfig, TtyPort, TIOCM_DTR, TIOCM_RTS};
+use crate::serial::{Owner, SerialError, DEFAULT_BAUD};
+use crate::syscall::EINVAL;
+
+pub const USB_PREFIX: &str = "/dev/ttyUSB";
+pub const ACM_PREFIX: &str = "/dev/ttyACM";
+
+pub const MAX_PORTS: usize = 8;
+
+const RX_SIZE: usize = 4096;
+const TX_SIZE: usize = 4096;
+// Largest bulk transfer each way per poll
+const TRANSFER_SIZE: usize = 512;
+
+/// The protocol an adapter speaks on its control pipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    /// CDC-ACM, on its communication interface
+    Acm { interface: u8 },
+    /// FTDI; the port is 0 on single-port chips and otherwise the
+    /// interface number plus one
+    Ftdi { port: u16 },
+    Cp210x { interface: u8 },
+}
+
+impl Chip {
+    pub fn name(self) -> &'static str {
+        match self {
+            Chip::Acm { .. } => "cdc_acm",
+            Chip::Ftdi { .. } => "ftdi_sio",
+            Chip::Cp210x { .. } => "cp210x",
+        }
+    }
+}
+
+/// An adapter attached to a port
+#[derive(Debug, Clone, Copy)]
+pub struct Link {
+    pub device: DeviceId,
+    pub address: u8,
+    pub chip: Chip,
+    pub bulk_in: u8,
+    pub bulk_out: u8,
+    pub interrupt_in: Option<u8>,
+    pub max_packet: usize,
+    pub baud: u32,
+    pub flow_control: bool,
+    /// TIOCM_* bits: DTR and RTS as set, the rest as last reported
+    pub lines: u32,
+}
+
+impl Link {
+    // Set the speed, flow control and DTR/RTS to what the link says
+    fn apply(&self) -> Result<(), &'static str> {
+        match self.chip {
+            Chip::Acm { .. } => acm::configure(self),
+            Chip::Ftdi { .. } => ftdi::configure(self),
+            Chip::Cp210x { .. } => cp210x::configure(self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortStats {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Bytes lost to a full receive ring
+    pub dropped: u64,
+    pub errors: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    pub name: &'static str,
+    pub index: usize,
+    pub chip: Chip,
+    pub address: u8,
+    pub baud: u32,
+    pub flow_control: bool,
+    pub owner: Owner,
+    pub rx_queued: usize,
+    pub tx_queued: usize,
+    pub stats: PortStats,
+}
+
+pub struct UsbSerialPort {
+    index: usize,
+    acm: bool,
+    rx: Ring<RX_SIZE>,
+    tx: Ring<TX_SIZE>,
+    // The bulk transfers, which make the holder the receive ring's producer
+    // and the transmit ring's consumer
+    io: Mutex<()>,
+    // The other side of each ring
+    reader: Mutex<()>,
+    writer: Mutex<()>,
+    link: Mutex<Option<Link>>,
+    owner: Mutex<Owner>,
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    dropped: AtomicU64,
+    errors: AtomicU64,
+}
+
+const fn port_table<const ACM: bool>() -> [UsbSerialPort; MAX_PORTS] {
+    let mut ports = [const { UsbSerialPort::new(ACM) }; MAX_PORTS];
+    let mut index = 0;
+    while index < MAX_PORTS {
+        ports[index].index = index;
+        index += 1;
+    }
+    ports
+}
+
+static USB_PORTS: [UsbSerialPort; MAX_PORTS] = port_table::<false>();
+static ACM_PORTS: [UsbSerialPort; MAX_PORTS] = port_table::<true>();
+
+// Serializes handing out ports
+static ATTACH: Mutex<()> = Mutex::new(());
+
+impl UsbSerialPort {
+    const fn new(acm: bool) -> Self {
+        UsbSerialPort {
+            index: 0,
+            acm,
+            rx: Ring::new(),
+            tx: Ring::new(),
+            io: Mutex::new(()),
+            reader: Mutex::new(()),
+            writer: Mutex::new(()),
+            link: Mutex::new(None),
+            owner: Mutex::new(Owner::Free),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// `ttyACM0`, `ttyUSB1` and so on
+    pub fn name(&self) -> alloc::string::String {
+        let prefix = if self.acm { ACM_PREFIX } else { USB_PREFIX };
+        alloc::format!("{}{}", prefix.trim_start_matches("/dev/"), self.index)
+    }
+
+    pub fn link(&self) -> Option<Link> {
+        *self.link.lock()
+    }
+
+    fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Take what the adapter has sent and send it what is queued
+    fn service(&self, link: &Link) {
+        let mut buffer = [0u8; TRANSFER_SIZE];
+        if self.rx.free() >= buffer.len() {
+            match crate::usb::bulk_transfer(link.address, link.bulk_in, &mut buffer, false) {
+                Ok(count) => {
+                    let mut received = [0u8; TRANSFER_SIZE];
+                    let payload = match link.chip {
+                        Chip::Ftdi { .. } => ftdi::strip_status(&buffer[..count], link.max_packet, &mut received),
+                        _ => {
+                            received[..count].copy_from_slice(&buffer[..count]);
+                            count
+                        }
+                    };
+                    let queued = self.rx.push_slice(&received[..payload]);
+                    self.rx_bytes.fetch_add(queued as u64, Ordering::Relaxed);
+                    self.dropped.fetch_add((payload - queued) as u64, Ordering::Relaxed);
+                }
+                Err(_) => self.error(),
+            }
+        }
+        if let Some(endpoint) = link.interrupt_in {
+            let mut notification = [0u8; 16];
+            if let Ok(count) = crate::usb::interrupt_transfer(link.address, endpoint, &mut notification) {
+                if let Some(status) = acm::serial_state(&notification[..count]) {
+                    self.update_lines(status);
+                }
+            }
+        }
+        let count = buffer.iter_mut().map_while(|slot| self.tx.pop().map(|byte| *slot = byte)).count();
+        if count > 0 {
+            match crate::usb::bulk_transfer(link.address, link.bulk_out, &mut buffer[..count], true) {
+                Ok(sent) => {
+                    self.tx_bytes.fetch_add(sent as u64, Ordering::Relaxed);
+                }
+                Err(_) => self.error(),
+            }
+        }
+    }
+
+    // Record the status lines an adapter reported, keeping DTR and RTS
+    fn update_lines(&self, status: u32) {
+        if let Some(link) = self.link.lock().as_mut() {
+            link.lines = link.lines & (TIOCM_DTR | TIOCM_RTS) | status & !(TIOCM_DTR | TIOCM_RTS);
+        }
+    }
+
+    // Change the link's settings and push them to the adapter, keeping the
+    // old ones if it refuses
+    fn reconfigure(&self, change: impl FnOnce(&mut Link)) -> Result<(), usize> {
+        let mut guard = self.link.lock();
+        let link = guard.as_mut().ok_or(crate::syscall::EIO)?;
+        let mut changed = *link;
+        change(&mut changed);
+        changed.apply().map_err(|_| EINVAL)?;
+        *link = changed;
+        Ok(())
+    }
+
+    pub fn info(&self) -> Option<PortInfo> {
+        let link = self.link()?;
+        Some(PortInfo {
+            name: if self.acm { "ttyACM" } else { "ttyUSB" },
+            index: self.index,
+            chip: link.chip,
+            address: link.address,
+            baud: link.baud,
+            flow_control: link.flow_control,
+            owner: *self.owner.lock(),
+            rx_queued: self.rx.len(),
+            tx_queued: self.tx.len(),
+            stats: PortStats {
+                rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+                tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+                dropped: self.dropped.load(Ordering::Relaxed),
+                errors: self.errors.load(Ordering::Relaxed),
+            },
+        })
+    }
+}
+
+impl TtyPort for UsbSerialPort {
+    fn poll(&self) {
+        let Some(link) = self.link() else {
+            return;
+        };
+        let _io = self.io.lock();
+        self.service(&link);
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> usize {
+        let _reader = self.reader.lock();
+        buffer.iter_mut().map_while(|slot| self.rx.pop().map(|byte| *slot = byte)).count()
+    }
+
+    fn write(&self, bytes: &[u8]) -> usize {
+        let queued = {
+            let _writer = self.writer.lock();
+            self.tx.push_slice(bytes)
+        };
+        self.poll();
+        queued
+    }
+
+    fn flush_input(&self) {
+        let _reader = self.reader.lock();
+        self.rx.clear();
+    }
+
+    fn flush_output(&self) {
+        let _io = self.io.lock();
+        while self.tx.pop().is_some() {}
+    }
+
+    fn rx_queued(&self) -> usize {
+        self.rx.len()
+    }
+
+    fn tx_queued(&self) -> usize {
+        self.tx.len()
+    }
+
+    fn config(&self) -> SerialConfig {
+        let link = self.link();
+        SerialConfig {
+            baud: link.map_or(DEFAULT_BAUD, |link| link.baud),
+            flow_control: link.is_some_and(|link| link.flow_control) as u32,
+        }
+    }
+
+    fn configure(&self, config: SerialConfig) -> Result<(), usize> {
+        self.reconfigure(|link| {
+            link.baud = config.baud;
+            link.flow_control = config.flow_control != 0;
+        })
+    }
+
+    fn modem_bits(&self) -> u32 {
+        let Some(link) = self.link() else {
+            return 0;
+        };
+        // CDC-ACM reports its lines by notification; the others are asked
+        match link.chip {
+            Chip::Acm { .. } => link.lines,
+            Chip::Ftdi { .. } => ftdi::modem_status(&link).map_or(link.lines, |status| {
+                self.update_lines(status);
+                link.lines & (TIOCM_DTR | TIOCM_RTS) | status
+            }),
+            Chip::Cp210x { .. } => cp210x::modem_status(&link).map_or(link.lines, |status| {
+                self.update_lines(status);
+                link.lines & (TIOCM_DTR | TIOCM_RTS) | status
+            }),
+        }
+    }
+
+    fn owner(&self) -> Owner {
+        *self.owner.lock()
+    }
+
+    fn claim(&self, owner: Owner) -> Result<(), SerialError> {
+        let mut current = self.owner.lock();
+        match *current {
+            _ if owner == Owner::Free => Ok(()),
+            Owner::Free => {
+                *current = owner;
+                Ok(())
+            }
+            held if held == owner => Ok(()),
+            _ => Err(SerialError::Busy),
+        }
+    }
+
+    fn release(&self, owner: Owner) {
+        let mut current = self.owner.lock();
+        if *current == owner {
+            *current = Owner::Free;
+        }
+    }
+}
+
+/// An attached `/dev/ttyUSB*` port
+pub fn usb_port(index: usize) -> Option<&'static dyn TtyPort> {
+    USB_PORTS.get(index).filter(|port| port.link().is_some()).map(|port| port as &dyn TtyPort)
+}
+
+/// An attached `/dev/ttyACM*` port
+pub fn acm_port(index: usize) -> Option<&'static dyn TtyPort> {
+    ACM_PORTS.get(index).filter(|port| port.link().is_some()).map(|port| port as &dyn TtyPort)
+}
+
+/// Every attached port, ttyACM first
+pub fn ports() -> impl Iterator<Item = &'static UsbSerialPort> {
+    ACM_PORTS.iter().chain(USB_PORTS.iter()).filter(|port| port.link().is_some())
+}
+
+/// Give an adapter the first free port and set its line up at the
+/// default speed with DTR and RTS raised
+pub fn attach(mut link: Link) -> Result<&'static UsbSerialPort, &'static str> {
+    link.baud = DEFAULT_BAUD;
+    link.flow_control = false;
+    link.lines = TIOCM_DTR | TIOCM_RTS;
+    link.apply()?;
+
+    let table = match link.chip {
+        Chip::Acm { .. } => &ACM_PORTS,
+        _ => &USB_PORTS,
+    };
+    let _attach = ATTACH.lock();
+    let port = table
+        .iter()
+        .find(|port| port.link().is_none() && *port.owner.lock() == Owner::Free)
+        .ok_or("every USB serial port is in use")?;
+    port.rx.clear();
+    {
+        let _io = port.io.lock();
+        while port.tx.pop().is_some() {}
+    }
+    *port.link.lock() = Some(link);
+    crate::serial_println!("usb-serial: {} adapter is {}", link.chip.name(), port.name());
+    Ok(port)
+}
+
+/// Take an unplugged adapter off its port
+pub fn detach(device: DeviceId) {
+    let _attach = ATTACH.lock();
+    for port in ACM_PORTS.iter().chain(USB_PORTS.iter()) {
+        let _io = port.io.lock();
+        let mut link = port.link.lock();
+        if link.is_some_and(|link| link.device == device) {
+            *link = None;
+            crate::serial_println!("usb-serial: {} disconnected", port.name());
+        }
+    }
+}
+
+/// Move bytes between every adapter and its port
+pub fn poll() {
+    for port in ports() {
+        TtyPort::poll(port);
+    }
+}
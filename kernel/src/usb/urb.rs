@@ -0,0 +1,326 @@
+//! USB request blocks: transfers submitted now and completed later
+//!
+//! A class driver builds an `Urb` for a control, bulk, interrupt or
+//! isochronous pipe, submits it and gets on with other work; when the
+//! transfer is done the URB's callback is called with it, buffer and all.
+//! Each endpoint has its own queue, started in order one URB at a time,
+//! and an interrupt or isochronous endpoint is started no more often than
+//! its descriptor's interval. An isochronous URB is a run of packets, one
+//! per interval, each with its own length and status: a packet lost is not
+//! the URB failing. A URB still waiting when its timeout passes completes as
+//! timed out, and one not yet started can be cancelled.
+//!
+//! The main loop runs the queues from `poll`, and callbacks are called
+//! from there with no USB lock held, so they may submit again. Until a
+//! controller queues transfers itself, a URB is carried out by the
+//! controller's `submit_urb` when its turn comes.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::Mutex;
+
+use super::{DeviceRequest, TransferType, UsbDevice, UsbSpeed, USB_MANAGER};
+use crate::time::clocksource::now_ns;
+
+pub type UrbId = u64;
+
+/// The pipe a URB goes over. An endpoint's address carries its direction.
+#[derive(Debug, Clone, Copy)]
+pub enum Pipe {
+    Control(DeviceRequest),
+    Bulk { endpoint: u8 },
+    Interrupt { endpoint: u8 },
+    Isochronous { endpoint: u8 },
+}
+
+impl Pipe {
+    // The endpoint address the pipe's queue is kept under
+    fn endpoint(&self) -> u8 {
+        match *self {
+            Pipe::Control(_) => 0,
+            Pipe::Bulk { endpoint } | Pipe::Interrupt { endpoint } | Pipe::Isochronous { endpoint } => endpoint,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrbStatus {
+    /// Submitted and not yet complete
+    Pending,
+    Completed,
+    Failed(&'static str),
+    Cancelled,
+    TimedOut,
+}
+
+/// One packet of an isochronous URB: where it sits in the buffer, and what
+/// came of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsoPacket {
+    pub offset: usize,
+    pub length: usize,
+    /// Bytes moved, once complete
+    pub actual: usize,
+    /// Completed, or Failed for a packet lost or damaged
+    pub status: UrbStatus,
+}
+
+type Callback = Box<dyn FnOnce(Urb) + Send>;
+
+pub struct Urb {
+    pub address: u8,
+    pub pipe: Pipe,
+    /// The data to send, or room for what comes back
+    pub buffer: Vec<u8>,
+    /// Bytes moved, once complete
+    pub actual: usize,
+    pub status: UrbStatus,
+    /// An isochronous URB's packets, in order; empty for other pipes
+    pub packets: Vec<IsoPacket>,
+    /// Milliseconds the URB may wait to start; 0 waits as long as it takes
+    pub timeout_ms: u64,
+    complete: Option<Callback>,
+}
+
+impl fmt::Debug for Urb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Urb")
+            .field("address", &self.address)
+            .field("pipe", &self.pipe)
+            .field("length", &self.buffer.len())
+            .field("actual", &self.actual)
+            .field("status", &self.status)
+            .field("packets", &self.packets.len())
+            .finish()
+    }
+}
+
+impl Urb {
+    fn new(address: u8, pipe: Pipe, buffer: Vec<u8>) -> Self {
+        Urb {
+            address,
+            pipe,
+            buffer,
+            actual: 0,
+            status: UrbStatus::Pending,
+            packets: Vec::new(),
+            timeout_ms: 0,
+            complete: None,
+        }
+    }
+
+    /// A control transfer; `buffer` is the data stage, as long as the
+    /// request's length
+    pub fn control(address: u8, request: DeviceRequest, buffer: Vec<u8>) -> Self {
+        Self::new(address, Pipe::Control(request), buffer)
+    }
+
+    pub fn bulk(address: u8, endpoint: u8, buffer: Vec<u8>) -> Self {
+        Self::new(address, Pipe::Bulk { endpoint }, buffer)
+    }
+
+    pub fn interrupt(address: u8, endpoint: u8, buffer: Vec<u8>) -> Self {
+        Self::new(address, Pipe::Interrupt { endpoint }, buffer)
+    }
+
+    /// An isochronous transfer of `count` packets of up to `size` bytes,
+    /// one per service interval
+    pub fn isochronous(address: u8, endpoint: u8, count: usize, size: usize) -> Self {
+        let mut urb = Self::new(address, Pipe::Isochronous { endpoint }, vec![0; count * size]);
+        urb.packets = (0..count)
+            .map(|index| IsoPacket { offset: index * size, length: size, actual: 0, status: UrbStatus::Pending })
+            .collect();
+        urb
+    }
+
+    /// What came back in each packet of an isochronous URB that arrived
+    /// whole
+    pub fn packet_data(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets
+            .iter()
+            .filter(|packet| packet.status == UrbStatus::Completed)
+            .map(|packet| &self.buffer[packet.offset..packet.offset + packet.actual.min(packet.length)])
+    }
+
+    pub fn with_timeout(mut self, timeout_ms: u64) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    /// Have `callback` called with the URB once it completes, however it
+    /// does
+    pub fn on_complete(mut self, callback: impl FnOnce(Urb) + Send + 'static) -> Self {
+        self.complete = Some(Box::new(callback));
+        self
+    }
+
+    /// What came back, or the part of the buffer that went out
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.actual.min(self.buffer.len())]
+    }
+
+    /// Whether the transfer goes from the host to the device
+    pub fn is_write(&self) -> bool {
+        match self.pipe {
+            Pipe::Control(request) => request.request_type & 0x80 == 0,
+            Pipe::Bulk { endpoint } | Pipe::Interrupt { endpoint } | Pipe::Isochronous { endpoint } => {
+                endpoint & 0x80 == 0
+            }
+        }
+    }
+
+    fn finish(mut self, status: UrbStatus) {
+        self.status = status;
+        if let Some(callback) = self.complete.take() {
+            callback(self);
+        }
+    }
+}
+
+struct Queued {
+    id: UrbId,
+    urb: Urb,
+    deadline: Option<u64>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    queue: VecDeque<Queued>,
+    // Not started again before this, for an interrupt endpoint's interval
+    next_start: u64,
+}
+
+struct Scheduler {
+    // By device address and endpoint address, 0 for the control pipe
+    endpoints: BTreeMap<(u8, u8), Endpoint>,
+    next_id: UrbId,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler { endpoints: BTreeMap::new(), next_id: 1 });
+
+/// Queue a URB on its endpoint; returns its ID, for `cancel`
+pub fn submit(urb: Urb) -> Result<UrbId, &'static str> {
+    if let Pipe::Control(request) = urb.pipe {
+        if urb.buffer.len() < request.length as usize {
+            return Err("URB buffer shorter than the request");
+        }
+    }
+    let deadline = (urb.timeout_ms != 0).then(|| now_ns() + urb.timeout_ms * 1_000_000);
+    let mut scheduler = SCHEDULER.lock();
+    let id = scheduler.next_id;
+    scheduler.next_id += 1;
+    let key = (urb.address, urb.pipe.endpoint());
+    scheduler.endpoints.entry(key).or_default().queue.push_back(Queued { id, urb, deadline });
+    Ok(id)
+}
+
+/// Cancel a URB that has not started; its callback is called, as
+/// cancelled. False if it has already started or completed.
+pub fn cancel(id: UrbId) -> bool {
+    let queued = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.endpoints.values_mut().find_map(|endpoint| {
+            let index = endpoint.queue.iter().position(|queued| queued.id == id)?;
+            endpoint.queue.remove(index)
+        })
+    };
+    match queued {
+        Some(queued) => {
+            queued.urb.finish(UrbStatus::Cancelled);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Cancel every URB waiting for the device at `address`, as when it is
+/// unplugged
+pub fn cancel_device(address: u8) {
+    let cancelled: Vec<Queued> = {
+        let mut scheduler = SCHEDULER.lock();
+        let keys: Vec<(u8, u8)> = scheduler.endpoints.keys().filter(|key| key.0 == address).copied().collect();
+        keys.iter().filter_map(|key| scheduler.endpoints.remove(key)).flat_map(|endpoint| endpoint.queue).collect()
+    };
+    for queued in cancelled {
+        queued.urb.finish(UrbStatus::Cancelled);
+    }
+}
+
+/// URBs waiting, on every endpoint
+pub fn pending() -> usize {
+    SCHEDULER.lock().endpoints.values().map(|endpoint| endpoint.queue.len()).sum()
+}
+
+// How often an interrupt or isochronous endpoint is to be served:
+// bInterval is in frames for interrupt endpoints at low and full speed, and
+// a power of two of frames or microframes otherwise
+fn interval_ns(device: &UsbDevice, endpoint: u8) -> u64 {
+    let Some(info) = device.endpoints.iter().find(|info| info.address == endpoint) else {
+        return 0;
+    };
+    let power = 1u64 << (info.interval.clamp(1, 16) - 1);
+    match (info.transfer_type, device.speed) {
+        (TransferType::Interrupt, UsbSpeed::Low | UsbSpeed::Full) => info.interval.max(1) as u64 * 1_000_000,
+        (TransferType::Isochronous, UsbSpeed::Low | UsbSpeed::Full) => power * 1_000_000,
+        (TransferType::Interrupt | TransferType::Isochronous, _) => power * 125_000,
+        _ => 0,
+    }
+}
+
+/// Time out the URBs left waiting too long and start the next on each
+/// endpoint that is ready; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    let (expired, due) = {
+        let mut scheduler = SCHEDULER.lock();
+        let mut expired = Vec::new();
+        let mut due = Vec::new();
+        for (key, endpoint) in scheduler.endpoints.iter_mut() {
+            let mut index = 0;
+            while index < endpoint.queue.len() {
+                if endpoint.queue[index].deadline.is_some_and(|deadline| deadline <= now) {
+                    expired.extend(endpoint.queue.remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+            if now >= endpoint.next_start {
+                if let Some(queued) = endpoint.queue.pop_front() {
+                    due.push((*key, queued));
+                }
+            }
+        }
+        scheduler.endpoints.retain(|_, endpoint| !endpoint.queue.is_empty() || endpoint.next_start > now);
+        (expired, due)
+    };
+
+    for queued in expired {
+        queued.urb.finish(UrbStatus::TimedOut);
+    }
+
+    for (key, queued) in due {
+        let mut urb = queued.urb;
+        // An isochronous URB takes an interval for each packet
+        let intervals = urb.packets.len().max(1) as u64;
+        let (result, interval) = {
+            let mut manager = USB_MANAGER.lock();
+            let interval = manager.device(urb.address).map_or(0, |device| interval_ns(device, key.1));
+            (manager.submit_urb(&mut urb), interval)
+        };
+        if interval != 0 {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.endpoints.entry(key).or_default().next_start = now + interval * intervals;
+        }
+        match result {
+            Ok(actual) => {
+                urb.actual = actual;
+                urb.finish(UrbStatus::Completed);
+            }
+            Err(e) => urb.finish(UrbStatus::Failed(e)),
+        }
+    }
+}
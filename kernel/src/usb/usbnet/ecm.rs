@@ -0,0 +1,64 @@
+//! CDC-ECM: Ethernet over USB, one frame per bulk transfer
+//!
+//! Most USB Ethernet dongles that need no driver of their own, and phones
+//! and boards sharing their connection, speak it. The MAC address is a
+//! string descriptor named by the Ethernet functional descriptor, and the
+//! data interface carries frames only once its second setting is chosen.
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::usb::cdc::{self, Function};
+use crate::usb::{USB_CLASS_CDC, USB_CLASS_MISC};
+
+use super::{Framing, UsbNet};
+
+/// Pass the adapter's own, broadcast and multicast frames, or everything
+pub fn set_packet_filter(address: u8, interface: u8, promiscuous: bool) -> Result<(), &'static str> {
+    let mut filter = cdc::PACKET_TYPE_DIRECTED | cdc::PACKET_TYPE_BROADCAST | cdc::PACKET_TYPE_ALL_MULTICAST;
+    if promiscuous {
+        filter |= cdc::PACKET_TYPE_PROMISCUOUS;
+    }
+    let request = cdc::class_request(cdc::SET_ETHERNET_PACKET_FILTER, filter, interface, 0, false);
+    crate::usb::control_transfer(address, &request, None).map(|_| ())
+}
+
+pub static USB_DRIVER: EcmDriver = EcmDriver;
+
+pub struct EcmDriver;
+
+impl Driver for EcmDriver {
+    fn name(&self) -> &'static str {
+        "cdc_ether"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbClass { class: USB_CLASS_CDC, subclass: None, protocol: None },
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(2), protocol: Some(1) },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let function = Function::find(&usb, cdc::SUBCLASS_ETHERNET).ok_or(ProbeError::NoDevice)?;
+        let data = function.data_setting(&usb).ok_or(ProbeError::Failed("no bulk endpoints"))?;
+        let mac = super::mac_from_string(address, &function).map_err(ProbeError::Failed)?;
+        crate::usb::set_interface(address, function.data, data.alternate).map_err(ProbeError::Failed)?;
+        set_packet_filter(address, function.control.number, false).map_err(ProbeError::Failed)?;
+
+        let net = UsbNet::new(device.id, address, &function.control, data, Framing::Ecm, mac);
+        let name = super::attach(net);
+        crate::serial_println!("cdc_ether: {} at USB address {}, {}", name, address, mac);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
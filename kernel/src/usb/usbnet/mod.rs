@@ -0,0 +1,214 @@
+//! USB network adapters
+//!
+//! CDC-ECM, CDC-NCM and RNDIS adapters, and phones and boards that share
+//! their connection as one, become network interfaces named `usb0`,
+//! `usb1` and so on. The three differ in how they set up and how frames
+//! are wrapped on the bulk pipes; see `ecm`, `ncm` and `rndis`. What they
+//! share is here: a `UsbNet` is the interface's `EthernetController`, and
+//! reads the bulk-in pipe when the stack asks for a frame and has none
+//! queued.
+//!
+//! RNDIS Wi-Fi adapters use the same data path, but are wireless
+//! interfaces; see `rndis_wlan`.
+//!
+//! The link is taken to be up until the adapter says otherwise with a
+//! NETWORK_CONNECTION notification, which RNDIS adapters do not send.
+
+pub mod ecm;
+pub mod ncm;
+pub mod rndis;
+pub mod rndis_wlan;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::driver::DeviceId;
+use crate::net::ethernet::{EthernetController, EthernetFrame, MacAddress};
+use crate::usb::{cdc, InterfaceInfo};
+
+// Frames held for the stack before the oldest are dropped
+const RX_QUEUE: usize = 64;
+// Room for one frame with its header and a VLAN tag
+const ECM_TRANSFER_SIZE: usize = 2048;
+
+/// How frames are wrapped
+#[derive(Debug, Clone, Copy)]
+pub enum Framing {
+    /// One frame per transfer, as it is
+    Ecm,
+    /// Frames gathered into NCM transfer blocks
+    Ncm(ncm::Params),
+    /// Each frame behind an RNDIS packet header
+    Rndis,
+}
+
+pub struct UsbNet {
+    pub device: DeviceId,
+    pub address: u8,
+    /// The communication interface, which takes class requests
+    pub control: u8,
+    pub framing: Framing,
+    pub bulk_in: u8,
+    pub bulk_out: u8,
+    pub interrupt_in: Option<u8>,
+    pub max_packet: usize,
+    pub mac: MacAddress,
+    /// Largest transfer the adapter sends
+    pub rx_size: usize,
+    pub link_up: bool,
+    // NCM's transfer block sequence number
+    pub sequence: u16,
+    rx: VecDeque<EthernetFrame>,
+}
+
+// The interface each adapter was registered as
+static BOUND: Mutex<Vec<(DeviceId, String)>> = Mutex::new(Vec::new());
+
+impl UsbNet {
+    pub fn new(
+        device: DeviceId,
+        address: u8,
+        control: &InterfaceInfo,
+        data: &InterfaceInfo,
+        framing: Framing,
+        mac: MacAddress,
+    ) -> Self {
+        let (bulk_in, bulk_out) = (data.bulk_in().unwrap(), data.bulk_out().unwrap());
+        let rx_size = match framing {
+            Framing::Ecm => ECM_TRANSFER_SIZE,
+            Framing::Ncm(params) => params.in_size,
+            Framing::Rndis => rndis::TRANSFER_SIZE,
+        };
+        UsbNet {
+            device,
+            address,
+            control: control.number,
+            framing,
+            bulk_in: bulk_in.address,
+            bulk_out: bulk_out.address,
+            interrupt_in: control.interrupt_in().map(|endpoint| endpoint.address),
+            max_packet: bulk_in.max_packet_size.max(1) as usize,
+            mac,
+            rx_size,
+            link_up: true,
+            sequence: 0,
+            rx: VecDeque::new(),
+        }
+    }
+
+    // Send a transfer, ending it with a zero-length packet when it fills
+    // its last packet exactly, so the adapter sees where it ends
+    fn send_transfer(&self, mut data: Vec<u8>) -> Result<(), &'static str> {
+        crate::usb::bulk_transfer(self.address, self.bulk_out, &mut data, true)?;
+        if data.len() % self.max_packet == 0 {
+            crate::usb::bulk_transfer(self.address, self.bulk_out, &mut [], true)?;
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, frame: EthernetFrame) {
+        if self.rx.len() == RX_QUEUE {
+            self.rx.pop_front();
+        }
+        self.rx.push_back(frame);
+    }
+
+    // Read one transfer from the adapter, and its notifications
+    fn receive(&mut self) {
+        if let Some(endpoint) = self.interrupt_in {
+            let mut notification = [0u8; 16];
+            if let Ok(count) = crate::usb::interrupt_transfer(self.address, endpoint, &mut notification) {
+                if count >= 8 && notification[1] == cdc::NOTIFY_NETWORK_CONNECTION {
+                    self.link_up = notification[2] != 0;
+                }
+            }
+        }
+        let mut buffer = vec![0u8; self.rx_size];
+        let Ok(count) = crate::usb::bulk_transfer(self.address, self.bulk_in, &mut buffer, false) else {
+            return;
+        };
+        let frames = match self.framing {
+            Framing::Ecm => EthernetFrame::from_bytes(&buffer[..count]).into_iter().collect(),
+            Framing::Ncm(_) => ncm::decode(&buffer[..count]),
+            Framing::Rndis => rndis::decode(&buffer[..count]),
+        };
+        for frame in frames {
+            self.queue(frame);
+        }
+    }
+
+    fn packet_filter(&self, promiscuous: bool) -> Result<(), &'static str> {
+        match self.framing {
+            Framing::Ecm | Framing::Ncm(_) => ecm::set_packet_filter(self.address, self.control, promiscuous),
+            Framing::Rndis => rndis::set_packet_filter(self.address, self.control, self.interrupt_in, promiscuous),
+        }
+    }
+}
+
+impl EthernetController for UsbNet {
+    fn get_mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send_frame(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        let bytes = frame.to_bytes();
+        let data = match self.framing {
+            Framing::Ecm => bytes,
+            Framing::Ncm(params) => {
+                self.sequence = self.sequence.wrapping_add(1);
+                ncm::encode(&params, self.sequence, &bytes)?
+            }
+            Framing::Rndis => rndis::encode(&bytes),
+        };
+        self.send_transfer(data)
+    }
+
+    fn receive_frame(&mut self) -> Option<EthernetFrame> {
+        if self.rx.is_empty() {
+            self.receive();
+        }
+        self.rx.pop_front()
+    }
+
+    fn set_promiscuous(&mut self, enabled: bool) {
+        if let Err(e) = self.packet_filter(enabled) {
+            crate::serial_println!("usbnet: cannot set the packet filter: {}", e);
+        }
+    }
+
+    fn get_link_status(&self) -> bool {
+        self.link_up
+    }
+}
+
+/// Register an adapter that has been set up as a network interface
+pub fn attach(net: UsbNet) -> String {
+    let device = net.device;
+    let name = crate::net::interface::register("usb", Box::new(net));
+    BOUND.lock().push((device, name.clone()));
+    name
+}
+
+/// Take an unplugged adapter's interface away
+pub fn detach(device: DeviceId) {
+    let mut bound = BOUND.lock();
+    bound.retain(|(id, name)| {
+        if *id == device {
+            crate::net::interface::unregister(name);
+            crate::serial_println!("usbnet: {} disconnected", name);
+        }
+        *id != device
+    });
+}
+
+/// The MAC address an ECM or NCM adapter gives as its iMACAddress string
+pub fn mac_from_string(address: u8, function: &cdc::Function) -> Result<MacAddress, &'static str> {
+    let (index, _) = function.ethernet().ok_or("no Ethernet functional descriptor")?;
+    let text = crate::usb::string_descriptor(address, index)?;
+    cdc::parse_mac(&text).map(MacAddress::new).ok_or("bad MAC address string")
+}
@@ -0,0 +1,173 @@
+//! CDC-NCM: Ethernet over USB with frames gathered into transfer blocks
+//!
+//! Set up as ECM is, plus GET_NTB_PARAMETERS for the block sizes and the
+//! alignment the adapter wants. Each block is an NTH16 header, then one or
+//! more NDP16 tables pointing at the frames in it. Frames are sent one to
+//! a block, and every frame of a block received is taken.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::net::ethernet::EthernetFrame;
+use crate::usb::cdc::{self, Function};
+use crate::usb::{USB_CLASS_CDC, USB_CLASS_MISC};
+
+use super::{Framing, UsbNet};
+
+// "NCMH", "NCM0" and "NCM1", which has a CRC after each frame
+const NTH16_SIGNATURE: u32 = 0x484D_434E;
+const NDP16_SIGNATURE: u32 = 0x304D_434E;
+const NDP16_CRC_SIGNATURE: u32 = 0x314D_434E;
+const NTH16_LENGTH: usize = 12;
+// Signature, length, next table, one frame and the terminating entry
+const NDP16_LENGTH: usize = 16;
+// Largest block taken from the adapter
+const MAX_IN_SIZE: usize = 16384;
+// Tables followed in one block, so a looping chain ends
+const MAX_TABLES: usize = 16;
+
+/// Block sizes and the alignment of frames sent
+#[derive(Debug, Clone, Copy)]
+pub struct Params {
+    pub in_size: usize,
+    pub out_size: usize,
+    pub out_divisor: usize,
+    pub out_remainder: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// GET_NTB_PARAMETERS, with the size taken in held to what fits here
+fn read_params(address: u8, interface: u8) -> Result<Params, &'static str> {
+    let mut reply = [0u8; 28];
+    let request = cdc::class_request(cdc::GET_NTB_PARAMETERS, 0, interface, reply.len() as u16, true);
+    crate::usb::control_transfer(address, &request, Some(&mut reply))?;
+    let field = |offset| u16_at(&reply, offset).unwrap_or(0) as usize;
+    let advertised = u32_at(&reply, 4).unwrap_or(0) as usize;
+    let in_size = advertised.min(MAX_IN_SIZE);
+    let params = Params {
+        in_size,
+        out_size: u32_at(&reply, 16).unwrap_or(0) as usize,
+        out_divisor: field(20).max(1),
+        out_remainder: field(22),
+    };
+    if params.in_size < NTH16_LENGTH + NDP16_LENGTH || params.out_size < NTH16_LENGTH + NDP16_LENGTH {
+        return Err("bad NTB parameters");
+    }
+    if in_size < advertised {
+        let mut size = (in_size as u32).to_le_bytes();
+        let request = cdc::class_request(cdc::SET_NTB_INPUT_SIZE, 0, interface, size.len() as u16, false);
+        crate::usb::control_transfer(address, &request, Some(&mut size))?;
+    }
+    Ok(params)
+}
+
+/// One frame as a transfer block
+pub fn encode(params: &Params, sequence: u16, frame: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut offset = NTH16_LENGTH + NDP16_LENGTH;
+    while offset % params.out_divisor != params.out_remainder % params.out_divisor {
+        offset += 1;
+    }
+    let length = offset + frame.len();
+    if length > params.out_size || length > u16::MAX as usize {
+        return Err("frame too large for the adapter's blocks");
+    }
+    let mut block = vec![0u8; length];
+    block[0..4].copy_from_slice(&NTH16_SIGNATURE.to_le_bytes());
+    block[4..6].copy_from_slice(&(NTH16_LENGTH as u16).to_le_bytes());
+    block[6..8].copy_from_slice(&sequence.to_le_bytes());
+    block[8..10].copy_from_slice(&(length as u16).to_le_bytes());
+    block[10..12].copy_from_slice(&(NTH16_LENGTH as u16).to_le_bytes());
+    let table = &mut block[NTH16_LENGTH..NTH16_LENGTH + NDP16_LENGTH];
+    table[0..4].copy_from_slice(&NDP16_SIGNATURE.to_le_bytes());
+    table[4..6].copy_from_slice(&(NDP16_LENGTH as u16).to_le_bytes());
+    table[8..10].copy_from_slice(&(offset as u16).to_le_bytes());
+    table[10..12].copy_from_slice(&(frame.len() as u16).to_le_bytes());
+    block[offset..].copy_from_slice(frame);
+    Ok(block)
+}
+
+/// The frames in a transfer block
+pub fn decode(block: &[u8]) -> Vec<EthernetFrame> {
+    let mut frames = Vec::new();
+    if u32_at(block, 0) != Some(NTH16_SIGNATURE) {
+        return frames;
+    }
+    let mut table = u16_at(block, 10).unwrap_or(0) as usize;
+    for _ in 0..MAX_TABLES {
+        if table == 0 {
+            break;
+        }
+        let signature = u32_at(block, table);
+        if signature != Some(NDP16_SIGNATURE) && signature != Some(NDP16_CRC_SIGNATURE) {
+            break;
+        }
+        let length = u16_at(block, table + 4).unwrap_or(0) as usize;
+        for entry in (table + 8..table + length).step_by(4) {
+            let (Some(index), Some(size)) = (u16_at(block, entry), u16_at(block, entry + 2)) else {
+                break;
+            };
+            if index == 0 || size == 0 {
+                break;
+            }
+            let Some(data) = block.get(index as usize..index as usize + size as usize) else {
+                continue;
+            };
+            if let Ok(frame) = EthernetFrame::from_bytes(data) {
+                frames.push(frame);
+            }
+        }
+        table = u16_at(block, table + 6).unwrap_or(0) as usize;
+    }
+    frames
+}
+
+pub static USB_DRIVER: NcmDriver = NcmDriver;
+
+pub struct NcmDriver;
+
+impl Driver for NcmDriver {
+    fn name(&self) -> &'static str {
+        "cdc_ncm"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbClass { class: USB_CLASS_CDC, subclass: None, protocol: None },
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(2), protocol: Some(1) },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let function = Function::find(&usb, cdc::SUBCLASS_NCM).ok_or(ProbeError::NoDevice)?;
+        let data = function.data_setting(&usb).ok_or(ProbeError::Failed("no bulk endpoints"))?;
+        let mac = super::mac_from_string(address, &function).map_err(ProbeError::Failed)?;
+        let params = read_params(address, function.control.number).map_err(ProbeError::Failed)?;
+        crate::usb::set_interface(address, function.data, data.alternate).map_err(ProbeError::Failed)?;
+        super::ecm::set_packet_filter(address, function.control.number, false).map_err(ProbeError::Failed)?;
+
+        let net = UsbNet::new(device.id, address, &function.control, data, Framing::Ncm(params), mac);
+        let name = super::attach(net);
+        crate::serial_println!("cdc_ncm: {} at USB address {}, {}", name, address, mac);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
@@ -0,0 +1,258 @@
+//! RNDIS: Microsoft's remote NDIS, which Android phones tethering over
+//! USB and many embedded boards speak
+//!
+//! Control messages go out with SEND_ENCAPSULATED_COMMAND and their
+//! replies come back with GET_ENCAPSULATED_RESPONSE. Setting up takes
+//! three: INITIALIZE, a QUERY of the permanent MAC address, and a SET of
+//! the packet filter, after which the adapter passes frames. Each frame on
+//! the bulk pipes follows a 44-byte PACKET_MSG header, and a transfer in
+//! may hold several.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::net::ethernet::{EthernetFrame, MacAddress};
+use crate::usb::cdc::{self, Function};
+use crate::usb::{USB_CLASS_CDC, USB_CLASS_MISC, USB_CLASS_WIRELESS};
+
+use super::{Framing, UsbNet};
+
+// Message types; a completion is its request's type with the top bit set
+const PACKET_MSG: u32 = 0x0000_0001;
+const INITIALIZE_MSG: u32 = 0x0000_0002;
+const QUERY_MSG: u32 = 0x0000_0004;
+const SET_MSG: u32 = 0x0000_0005;
+const COMPLETION: u32 = 0x8000_0000;
+
+const STATUS_SUCCESS: u32 = 0;
+
+const OID_802_3_PERMANENT_ADDRESS: u32 = 0x0101_0101;
+const OID_GEN_CURRENT_PACKET_FILTER: u32 = 0x0001_010E;
+const OID_GEN_PHYSICAL_MEDIUM: u32 = 0x0001_0202;
+
+// OID_GEN_PHYSICAL_MEDIUM's value for a wireless adapter
+const MEDIUM_WIRELESS_LAN: u32 = 1;
+
+// OID_GEN_CURRENT_PACKET_FILTER bits
+const FILTER_DIRECTED: u32 = 0x01;
+const FILTER_ALL_MULTICAST: u32 = 0x04;
+const FILTER_BROADCAST: u32 = 0x08;
+const FILTER_PROMISCUOUS: u32 = 0x20;
+
+const PACKET_HEADER_LENGTH: usize = 44;
+// Offsets in a message's header are counted from this field on
+const OFFSET_BASE: usize = 8;
+
+/// Largest transfer the adapter is told it may send
+pub const TRANSFER_SIZE: usize = 16384;
+/// Largest reply to most control messages
+pub const RESPONSE_SIZE: usize = 1025;
+// Times to ask for a reply before giving up on it
+const RESPONSE_TRIES: usize = 16;
+
+// Control interfaces that are RNDIS: wireless controller, miscellaneous,
+// and CDC ACM with the vendor protocol, by class, subclass and protocol
+const CONTROL_INTERFACES: [(u8, u8, u8); 3] =
+    [(USB_CLASS_WIRELESS, 1, 3), (USB_CLASS_MISC, 4, 1), (USB_CLASS_CDC, 2, 0xFF)];
+
+static NEXT_REQUEST: AtomicU32 = AtomicU32::new(1);
+
+pub(super) fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// A control message: its type, length, a request ID and then `fields`
+fn message(kind: u32, fields: &[u32], payload: &[u8]) -> (u32, Vec<u8>) {
+    let request = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    let length = 12 + fields.len() * 4 + payload.len();
+    let mut data = Vec::with_capacity(length);
+    for value in [kind, length as u32, request].iter().chain(fields) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(payload);
+    (request, data)
+}
+
+// Send a control message and wait for its completion, of up to `size`
+// bytes, returning it
+fn command(
+    address: u8,
+    interface: u8,
+    interrupt_in: Option<u8>,
+    kind: u32,
+    fields: &[u32],
+    payload: &[u8],
+    size: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let (request, mut data) = message(kind, fields, payload);
+    let send = cdc::class_request(cdc::SEND_ENCAPSULATED_COMMAND, 0, interface, data.len() as u16, false);
+    crate::usb::control_transfer(address, &send, Some(&mut data))?;
+
+    let mut reply = vec![0u8; size];
+    for _ in 0..RESPONSE_TRIES {
+        // The adapter says a reply is ready; read the notice so it can
+        // send the next one, whether or not it has
+        if let Some(endpoint) = interrupt_in {
+            let mut notification = [0u8; 8];
+            let _ = crate::usb::interrupt_transfer(address, endpoint, &mut notification);
+        }
+        let get = cdc::class_request(cdc::GET_ENCAPSULATED_RESPONSE, 0, interface, reply.len() as u16, true);
+        let count = crate::usb::control_transfer(address, &get, Some(&mut reply))?;
+        let reply = &reply[..count];
+        if u32_at(reply, 0) == Some(kind | COMPLETION) && u32_at(reply, 8) == Some(request) {
+            return match u32_at(reply, 12) {
+                Some(STATUS_SUCCESS) => Ok(reply.to_vec()),
+                _ => Err("RNDIS request refused"),
+            };
+        }
+    }
+    Err("no RNDIS reply")
+}
+
+fn initialize(address: u8, interface: u8, interrupt_in: Option<u8>) -> Result<(), &'static str> {
+    // Version 1.0, and the largest transfer taken in
+    let fields = [1, 0, TRANSFER_SIZE as u32];
+    command(address, interface, interrupt_in, INITIALIZE_MSG, &fields, &[], RESPONSE_SIZE).map(|_| ())
+}
+
+/// What the adapter answers a QUERY of `oid` with, in a reply of up to
+/// `size` bytes
+pub fn query(
+    address: u8,
+    interface: u8,
+    interrupt_in: Option<u8>,
+    oid: u32,
+    size: usize,
+) -> Result<Vec<u8>, &'static str> {
+    // OID, no input buffer, and no virtual channel
+    let reply = command(address, interface, interrupt_in, QUERY_MSG, &[oid, 0, 0, 0], &[], size)?;
+    let length = u32_at(&reply, 16).ok_or("short RNDIS reply")? as usize;
+    let offset = u32_at(&reply, 20).ok_or("short RNDIS reply")? as usize + OFFSET_BASE;
+    reply.get(offset..offset + length).map(<[u8]>::to_vec).ok_or("short RNDIS reply")
+}
+
+/// SET `oid` to `value`
+pub fn set(address: u8, interface: u8, interrupt_in: Option<u8>, oid: u32, value: &[u8]) -> Result<(), &'static str> {
+    // OID, the buffer's length and where it starts, and no virtual channel
+    let fields = [oid, value.len() as u32, 20, 0];
+    command(address, interface, interrupt_in, SET_MSG, &fields, value, RESPONSE_SIZE).map(|_| ())
+}
+
+fn permanent_address(address: u8, interface: u8, interrupt_in: Option<u8>) -> Result<MacAddress, &'static str> {
+    let value = query(address, interface, interrupt_in, OID_802_3_PERMANENT_ADDRESS, RESPONSE_SIZE)?;
+    value.get(..6).and_then(MacAddress::from_bytes).ok_or("no MAC address in RNDIS reply")
+}
+
+/// Pass the adapter's own, broadcast and multicast frames, or everything
+pub fn set_packet_filter(
+    address: u8,
+    interface: u8,
+    interrupt_in: Option<u8>,
+    promiscuous: bool,
+) -> Result<(), &'static str> {
+    let mut filter = FILTER_DIRECTED | FILTER_BROADCAST | FILTER_ALL_MULTICAST;
+    if promiscuous {
+        filter |= FILTER_PROMISCUOUS;
+    }
+    set(address, interface, interrupt_in, OID_GEN_CURRENT_PACKET_FILTER, &filter.to_le_bytes())
+}
+
+/// A frame behind its PACKET_MSG header
+pub fn encode(frame: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; PACKET_HEADER_LENGTH];
+    // Type, length, where the frame starts, and its length
+    let fields = [
+        PACKET_MSG,
+        (PACKET_HEADER_LENGTH + frame.len()) as u32,
+        (PACKET_HEADER_LENGTH - OFFSET_BASE) as u32,
+        frame.len() as u32,
+    ];
+    for (slot, value) in data.chunks_mut(4).zip(fields) {
+        slot.copy_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(frame);
+    data
+}
+
+/// The frames in a transfer
+pub fn decode(transfer: &[u8]) -> Vec<EthernetFrame> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while let (Some(PACKET_MSG), Some(length)) = (u32_at(transfer, offset), u32_at(transfer, offset + 4)) {
+        let length = length as usize;
+        let packet = transfer.get(offset..offset + length).unwrap_or(&transfer[offset..]);
+        let start = u32_at(packet, 8).unwrap_or(0) as usize + OFFSET_BASE;
+        let size = u32_at(packet, 12).unwrap_or(0) as usize;
+        if let Some(Ok(frame)) = packet.get(start..start + size).map(EthernetFrame::from_bytes) {
+            frames.push(frame);
+        }
+        if length < PACKET_HEADER_LENGTH {
+            break;
+        }
+        offset += length;
+    }
+    frames
+}
+
+/// Find a device's RNDIS function and set it up to pass frames
+pub fn open(device: &Device) -> Result<UsbNet, ProbeError> {
+    let Ident::Usb { address, .. } = device.ident else {
+        return Err(ProbeError::NoDevice);
+    };
+    let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+    let control = usb
+        .interfaces
+        .iter()
+        .find(|interface| CONTROL_INTERFACES.contains(&(interface.class, interface.subclass, interface.protocol)))
+        .ok_or(ProbeError::NoDevice)?;
+    let function = Function::with_control(&usb, control).ok_or(ProbeError::Failed("no data interface"))?;
+    let data = function.data_setting(&usb).ok_or(ProbeError::Failed("no bulk endpoints"))?;
+    let interrupt_in = control.interrupt_in().map(|endpoint| endpoint.address);
+    initialize(address, control.number, interrupt_in).map_err(ProbeError::Failed)?;
+    let mac = permanent_address(address, control.number, interrupt_in).map_err(ProbeError::Failed)?;
+    set_packet_filter(address, control.number, interrupt_in, false).map_err(ProbeError::Failed)?;
+    Ok(UsbNet::new(device.id, address, control, data, Framing::Rndis, mac))
+}
+
+pub static USB_DRIVER: RndisDriver = RndisDriver;
+
+pub struct RndisDriver;
+
+impl Driver for RndisDriver {
+    fn name(&self) -> &'static str {
+        "rndis_host"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            Match::UsbClass { class: USB_CLASS_WIRELESS, subclass: Some(1), protocol: Some(3) },
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(4), protocol: Some(1) },
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(2), protocol: Some(1) },
+            Match::UsbClass { class: USB_CLASS_CDC, subclass: None, protocol: None },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let net = open(device)?;
+        // Wireless adapters pass nothing until told which network to join,
+        // which is rndis_wlan's to do, for the ones it knows
+        let medium = query(net.address, net.control, net.interrupt_in, OID_GEN_PHYSICAL_MEDIUM, RESPONSE_SIZE);
+        if medium.is_ok_and(|value| u32_at(&value, 0) == Some(MEDIUM_WIRELESS_LAN)) {
+            return Err(ProbeError::Failed("wireless adapter not known to rndis_wlan"));
+        }
+        let (address, mac) = (net.address, net.mac);
+        let name = super::attach(net);
+        crate::serial_println!("rndis_host: {} at USB address {}, {}", name, address, mac);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        super::detach(device.id);
+    }
+}
@@ -0,0 +1,339 @@
+//! Wi-Fi adapters that speak RNDIS: the Broadcom 4320 USB ones
+//!
+//! These run the 802.11 MAC in their firmware and pass Ethernet frames on
+//! the RNDIS bulk pipes like any other RNDIS adapter, so the data path is
+//! `UsbNet`'s. What they add are the NDIS 802.11 OIDs: a scan is a SET of
+//! BSSID_LIST_SCAN and, a few seconds later, a QUERY of BSSID_LIST;
+//! joining is setting the authentication and encryption modes and then
+//! the SSID; the keys the stack's WPA2 handshake ends with go back with
+//! ADD_KEY. The adapter sends no event when it has associated, so the
+//! media connect status is asked for until it says so.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::rndis::{self, u32_at, RESPONSE_SIZE};
+use super::UsbNet;
+use crate::driver::{BusType, Device, DeviceId, Driver, Ident, Match, ProbeError};
+use crate::net::ethernet::{EthernetController, EthernetFrame, MacAddress};
+use crate::net::wireless::cfg80211::{self, Bss, Event, KeyKind, WirelessDriver, ELEMENT_RSN};
+
+const OID_GEN_MEDIA_CONNECT_STATUS: u32 = 0x0001_0114;
+const OID_802_11_BSSID: u32 = 0x0D01_0101;
+const OID_802_11_SSID: u32 = 0x0D01_0102;
+const OID_802_11_INFRASTRUCTURE_MODE: u32 = 0x0D01_0108;
+const OID_802_11_DISASSOCIATE: u32 = 0x0D01_0115;
+const OID_802_11_AUTHENTICATION_MODE: u32 = 0x0D01_0118;
+const OID_802_11_PRIVACY_FILTER: u32 = 0x0D01_0119;
+const OID_802_11_BSSID_LIST_SCAN: u32 = 0x0D01_011A;
+const OID_802_11_ENCRYPTION_STATUS: u32 = 0x0D01_011B;
+const OID_802_11_ADD_KEY: u32 = 0x0D01_011D;
+const OID_802_11_ASSOCIATION_INFORMATION: u32 = 0x0D01_011F;
+const OID_802_11_BSSID_LIST: u32 = 0x0D01_0217;
+
+// OID values
+const MEDIA_CONNECTED: u32 = 0;
+const INFRASTRUCTURE: u32 = 1;
+const AUTH_OPEN: u32 = 0;
+const AUTH_WPA2_PSK: u32 = 7;
+const PRIVACY_ACCEPT_ALL: u32 = 0;
+const PRIVACY_8021X: u32 = 1;
+const ENCRYPTION_DISABLED: u32 = 1;
+const ENCRYPTION_AES: u32 = 6;
+
+// ADD_KEY's index flags
+const KEY_TRANSMIT: u32 = 1 << 31;
+const KEY_PAIRWISE: u32 = 1 << 30;
+const KEY_RSC: u32 = 1 << 29;
+// ADD_KEY's fields before the key material
+const KEY_HEADER_LENGTH: usize = 32;
+
+// Where an NDIS_WLAN_BSSID_EX's fields are
+const BSS_LENGTH: usize = 0;
+const BSS_MAC: usize = 4;
+const BSS_SSID_LENGTH: usize = 12;
+const BSS_SSID: usize = 16;
+const BSS_RSSI: usize = 52;
+const BSS_DS_CONFIG: usize = 72;
+const BSS_IE_LENGTH: usize = 112;
+const BSS_IES: usize = 116;
+// The IEs follow the beacon's timestamp, interval and capability
+const FIXED_IES: usize = 12;
+
+// Room for the scan results of a busy neighbourhood
+const BSSID_LIST_SIZE: usize = 16384;
+// How long a scan takes, how long to wait to be associated, and how often
+// to ask whether it has been
+const SCAN_MS: u64 = 4000;
+const ASSOCIATE_MS: u64 = 10_000;
+const STATUS_MS: u64 = 500;
+
+// The interface each adapter was registered as
+static ATTACHED: Mutex<Vec<(DeviceId, String)>> = Mutex::new(Vec::new());
+
+fn now_ms() -> u64 {
+    crate::time::clocksource::now_ns() / 1_000_000
+}
+
+/// The networks in a BSSID_LIST
+fn parse_bssid_list(list: &[u8]) -> Vec<Bss> {
+    let mut found = Vec::new();
+    let count = u32_at(list, 0).unwrap_or(0);
+    let mut offset = 4;
+    for _ in 0..count {
+        let Some(length) = u32_at(list, offset + BSS_LENGTH).map(|length| length as usize) else {
+            break;
+        };
+        let Some(entry) = list.get(offset..offset + length).filter(|_| length >= BSS_IES) else {
+            break;
+        };
+        offset += length;
+
+        let ssid_length = (u32_at(entry, BSS_SSID_LENGTH).unwrap_or(0) as usize).min(32);
+        let ies_length = u32_at(entry, BSS_IE_LENGTH).unwrap_or(0) as usize;
+        let Some(ies) = entry.get(BSS_IES..BSS_IES + ies_length).filter(|ies| ies.len() >= FIXED_IES) else {
+            continue;
+        };
+        found.push(Bss {
+            bssid: MacAddress::from_bytes(&entry[BSS_MAC..BSS_MAC + 6]).unwrap(),
+            ssid: entry[BSS_SSID..BSS_SSID + ssid_length].to_vec(),
+            // The frequency, in kHz
+            channel: cfg80211::frequency_channel(u32_at(entry, BSS_DS_CONFIG).unwrap_or(0) / 1000),
+            signal: u32_at(entry, BSS_RSSI).unwrap_or(0) as i32,
+            capability: u16::from_le_bytes([ies[10], ies[11]]),
+            elements: ies[FIXED_IES..].to_vec(),
+        });
+    }
+    found.sort_by_key(|bss| -bss.signal);
+    found
+}
+
+pub struct RndisWlan {
+    net: UsbNet,
+    /// When the scan asked for is done
+    scan_until: Option<u64>,
+    /// The network being joined, the RSN element asked for, and when to
+    /// give up
+    joining: Option<(MacAddress, Option<Vec<u8>>, u64)>,
+    /// The network joined
+    joined: Option<MacAddress>,
+    next_status: u64,
+    events: VecDeque<Event>,
+}
+
+impl RndisWlan {
+    fn query(&self, oid: u32, size: usize) -> Result<Vec<u8>, &'static str> {
+        rndis::query(self.net.address, self.net.control, self.net.interrupt_in, oid, size)
+    }
+
+    fn set(&self, oid: u32, value: &[u8]) -> Result<(), &'static str> {
+        rndis::set(self.net.address, self.net.control, self.net.interrupt_in, oid, value)
+    }
+
+    fn set_u32(&self, oid: u32, value: u32) -> Result<(), &'static str> {
+        self.set(oid, &value.to_le_bytes())
+    }
+
+    fn media_connected(&self) -> bool {
+        self.query(OID_GEN_MEDIA_CONNECT_STATUS, RESPONSE_SIZE)
+            .is_ok_and(|value| u32_at(&value, 0) == Some(MEDIA_CONNECTED))
+    }
+
+    // The RSN element the adapter sent in its association request, which
+    // the handshake has to repeat
+    fn association_rsn(&self) -> Option<Vec<u8>> {
+        let info = self.query(OID_802_11_ASSOCIATION_INFORMATION, RESPONSE_SIZE).ok()?;
+        let (length, offset) = (u32_at(&info, 16)? as usize, u32_at(&info, 20)? as usize);
+        let ies = info.get(offset..offset + length)?;
+        cfg80211::element(ies, ELEMENT_RSN).map(<[u8]>::to_vec)
+    }
+
+    // See whether the adapter has joined the network, or left it
+    fn check_status(&mut self) {
+        let now = now_ms();
+        if now < self.next_status {
+            return;
+        }
+        self.next_status = now + STATUS_MS;
+        let connected = self.media_connected();
+        if let Some((bssid, rsn, deadline)) = self.joining.take() {
+            if connected {
+                let rsn = self.association_rsn().or(rsn);
+                let bssid = self
+                    .query(OID_802_11_BSSID, RESPONSE_SIZE)
+                    .ok()
+                    .and_then(|value| value.get(..6).and_then(MacAddress::from_bytes))
+                    .unwrap_or(bssid);
+                self.joined = Some(bssid);
+                self.events.push_back(Event::Connected { bssid, rsn });
+            } else if now >= deadline {
+                let _ = self.set(OID_802_11_DISASSOCIATE, &[]);
+                self.events.push_back(Event::ConnectFailed("not associated"));
+            } else {
+                self.joining = Some((bssid, rsn, deadline));
+            }
+        } else if self.joined.is_some() && !connected {
+            self.joined = None;
+            // The adapter keeps the reason to itself
+            self.events.push_back(Event::Disconnected { reason: 0 });
+        }
+    }
+}
+
+impl WirelessDriver for RndisWlan {
+    fn mac_address(&self) -> MacAddress {
+        self.net.mac
+    }
+
+    fn scan(&mut self) -> Result<(), &'static str> {
+        if self.scan_until.is_some() {
+            return Err("busy");
+        }
+        self.set_u32(OID_802_11_BSSID_LIST_SCAN, 0)?;
+        self.scan_until = Some(now_ms() + SCAN_MS);
+        Ok(())
+    }
+
+    fn connect(&mut self, bss: &Bss, rsn: Option<&[u8]>) -> Result<(), &'static str> {
+        let (auth, privacy, encryption) = match rsn {
+            Some(_) => (AUTH_WPA2_PSK, PRIVACY_8021X, ENCRYPTION_AES),
+            None => (AUTH_OPEN, PRIVACY_ACCEPT_ALL, ENCRYPTION_DISABLED),
+        };
+        self.set_u32(OID_802_11_INFRASTRUCTURE_MODE, INFRASTRUCTURE)?;
+        self.set_u32(OID_802_11_AUTHENTICATION_MODE, auth)?;
+        self.set_u32(OID_802_11_PRIVACY_FILTER, privacy)?;
+        self.set_u32(OID_802_11_ENCRYPTION_STATUS, encryption)?;
+        self.set(OID_802_11_BSSID, bss.bssid.as_bytes())?;
+        // Setting the SSID is what starts the adapter joining
+        let mut ssid = [0u8; 36];
+        let length = bss.ssid.len().min(32);
+        ssid[..4].copy_from_slice(&(length as u32).to_le_bytes());
+        ssid[4..4 + length].copy_from_slice(&bss.ssid[..length]);
+        self.set(OID_802_11_SSID, &ssid)?;
+
+        self.joined = None;
+        self.joining = Some((bss.bssid, rsn.map(<[u8]>::to_vec), now_ms() + ASSOCIATE_MS));
+        self.next_status = now_ms() + STATUS_MS;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Err(e) = self.set(OID_802_11_DISASSOCIATE, &[]) {
+            crate::serial_println!("rndis_wlan: cannot disassociate: {}", e);
+        }
+        self.joining = None;
+        self.joined = None;
+    }
+
+    fn add_key(&mut self, kind: KeyKind, key: &[u8]) -> Result<(), &'static str> {
+        let bssid = self.joined.ok_or("not associated")?;
+        let (index, bssid, rsc) = match kind {
+            KeyKind::Pairwise => (KEY_TRANSMIT | KEY_PAIRWISE, bssid, 0),
+            KeyKind::Group { index, rsc } => (index as u32 | KEY_RSC, MacAddress::BROADCAST, rsc),
+        };
+        // Its size, the index and flags, the key's length, the BSSID and
+        // padding, the receive sequence counter, and the key
+        let size = KEY_HEADER_LENGTH + key.len();
+        let mut value = Vec::with_capacity(size);
+        value.extend_from_slice(&(size as u32).to_le_bytes());
+        value.extend_from_slice(&index.to_le_bytes());
+        value.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        value.extend_from_slice(bssid.as_bytes());
+        value.extend_from_slice(&[0; 6]);
+        value.extend_from_slice(&rsc.to_le_bytes());
+        value.extend_from_slice(key);
+        self.set(OID_802_11_ADD_KEY, &value)
+    }
+
+    fn send(&mut self, frame: &EthernetFrame) -> Result<(), &'static str> {
+        self.net.send_frame(frame)
+    }
+
+    fn poll(&mut self) -> Option<Event> {
+        if self.scan_until.is_some_and(|until| now_ms() >= until) {
+            self.scan_until = None;
+            let list = self.query(OID_802_11_BSSID_LIST, BSSID_LIST_SIZE).unwrap_or_default();
+            self.events.push_back(Event::ScanDone(parse_bssid_list(&list)));
+        }
+        if self.joining.is_some() || self.joined.is_some() {
+            self.check_status();
+        }
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        // Frames only come once the adapter has joined a network
+        if self.joined.is_some() {
+            return self.net.receive_frame().map(Event::Frame);
+        }
+        None
+    }
+}
+
+pub static USB_DRIVER: RndisWlanDriver = RndisWlanDriver;
+
+pub struct RndisWlanDriver;
+
+impl Driver for RndisWlanDriver {
+    fn name(&self) -> &'static str {
+        "rndis_wlan"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // Buffalo WLI-U2-KG125S
+            Match::UsbId { vendor: 0x0411, product: 0x00BC },
+            // U.S. Robotics USR5421
+            Match::UsbId { vendor: 0x0BAF, product: 0x011B },
+            // Belkin F5D7051
+            Match::UsbId { vendor: 0x050D, product: 0x011B },
+            Match::UsbId { vendor: 0x1799, product: 0x011B },
+            // Linksys WUSB54GS, WUSB54GSC and their second versions
+            Match::UsbId { vendor: 0x13B1, product: 0x000B },
+            Match::UsbId { vendor: 0x13B1, product: 0x0011 },
+            Match::UsbId { vendor: 0x13B1, product: 0x000E },
+            Match::UsbId { vendor: 0x13B1, product: 0x0026 },
+            // ASUS WL169gE
+            Match::UsbId { vendor: 0x0B05, product: 0x1717 },
+            // Eminent EM4045
+            Match::UsbId { vendor: 0x0A5C, product: 0xD11B },
+            // BT Voyager 1055
+            Match::UsbId { vendor: 0x1690, product: 0x0715 },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let net = rndis::open(device)?;
+        let wlan =
+            RndisWlan { net, scan_until: None, joining: None, joined: None, next_status: 0, events: VecDeque::new() };
+        // Start from no network, whatever the adapter last joined
+        wlan.set_u32(OID_802_11_INFRASTRUCTURE_MODE, INFRASTRUCTURE).map_err(ProbeError::Failed)?;
+        let _ = wlan.set(OID_802_11_DISASSOCIATE, &[]);
+
+        let mac = wlan.net.mac;
+        let name = crate::net::wireless::attach(Box::new(wlan), format!("rndis_wlan at USB address {}", address));
+        crate::serial_println!("rndis_wlan: {} at USB address {}, {}", name, address, mac);
+        ATTACHED.lock().push((device.id, name));
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        ATTACHED.lock().retain(|(id, name)| {
+            if *id == device.id {
+                crate::net::wireless::detach(name);
+            }
+            *id != device.id
+        });
+    }
+}
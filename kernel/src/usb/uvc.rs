@@ -0,0 +1,797 @@
+//! USB Video Class cameras
+//!
+//! A webcam has a control interface and a streaming interface. The
+//! streaming interface's class descriptors list its formats, each with its
+//! frame sizes; YUY2, NV12 and MJPEG are taken, other formats skipped. To
+//! stream, the format, frame size and interval are offered through the
+//! probe control, the camera's answer read back and committed, and the
+//! streaming interface switched to the smallest alternate setting whose
+//! isochronous endpoint carries the payload size the camera asked for.
+//! Cameras with a bulk endpoint stream on that instead.
+//!
+//! Each payload starts with a header. Its frame ID bit flips at every new
+//! frame and its end-of-frame bit marks the last payload of one, so a
+//! frame is ended by either; a frame with the error bit set in any payload,
+//! or that is not the size its format says, is dropped. Whole frames wait
+//! in a short queue, oldest dropped first, for `capture_frame`.
+//!
+//! Each camera is a `CaptureDevice` in the capture manager while it is
+//! plugged in.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use spin::{Mutex, RwLock};
+
+use super::urb::{self, Urb, UrbId, UrbStatus};
+use super::{DeviceRequest, InterfaceInfo, USB_CLASS_MISC, USB_CLASS_VIDEO};
+use crate::driver::{BusType, Device, Driver, Ident, Match, ProbeError};
+use crate::multimedia::capture::{
+    CaptureDevice, CapturedFrame, DeviceCapabilities, DeviceType, VideoFrame, CAPTURE_MANAGER,
+};
+use crate::multimedia::{MediaError, MediaFormat, MediaType, PixelFormat};
+use crate::serial_println;
+
+// Interface subclasses
+const SC_VIDEOCONTROL: u8 = 0x01;
+const SC_VIDEOSTREAMING: u8 = 0x02;
+// Cameras that group their interfaces with an interface association
+const MISC_COMMON: u8 = 0x02;
+const MISC_IAD: u8 = 0x01;
+
+// Class-specific descriptors
+const CS_INTERFACE: u8 = 0x24;
+const VC_HEADER: u8 = 0x01;
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+
+// Streaming interface controls, and the requests that read and write them
+const VS_PROBE_CONTROL: u16 = 0x01;
+const VS_COMMIT_CONTROL: u16 = 0x02;
+const SET_CUR: u8 = 0x01;
+const GET_CUR: u8 = 0x81;
+
+// Payload header bits
+const HEADER_FID: u8 = 0x01;
+const HEADER_EOF: u8 = 0x02;
+const HEADER_ERR: u8 = 0x40;
+
+// Transfers kept waiting while streaming, and packets in each isochronous
+// one
+const URBS: usize = 2;
+const PACKETS: usize = 32;
+// Frames kept for `capture_frame`
+const QUEUE: usize = 4;
+// Frame sizes beyond this are not picked unless there is nothing smaller
+const DEFAULT_WIDTH: u16 = 640;
+const DEFAULT_HEIGHT: u16 = 480;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Yuyv,
+    Nv12,
+    Mjpeg,
+}
+
+impl Encoding {
+    fn pixel_format(self) -> PixelFormat {
+        match self {
+            Encoding::Yuyv => PixelFormat::YUYV,
+            Encoding::Nv12 => PixelFormat::NV12,
+            Encoding::Mjpeg => PixelFormat::MJPEG,
+        }
+    }
+
+    // The bytes a frame of this size takes, if that is fixed
+    fn frame_size(self, width: u16, height: u16) -> Option<usize> {
+        let pixels = width as usize * height as usize;
+        match self {
+            Encoding::Yuyv => Some(pixels * 2),
+            Encoding::Nv12 => Some(pixels * 3 / 2),
+            Encoding::Mjpeg => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameSize {
+    index: u8,
+    width: u16,
+    height: u16,
+    /// In 100ns units
+    interval: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Format {
+    index: u8,
+    encoding: Encoding,
+    frames: Vec<FrameSize>,
+}
+
+// What was chosen to stream
+#[derive(Debug, Clone, Copy)]
+struct Selection {
+    format: u8,
+    encoding: Encoding,
+    frame: FrameSize,
+}
+
+impl Selection {
+    fn media_format(&self) -> MediaFormat {
+        media_format(self.encoding, &self.frame)
+    }
+}
+
+// Where streamed data comes in
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Isochronous { alternate: u8, endpoint: u8, packet_size: usize },
+    Bulk { endpoint: u8, size: usize },
+}
+
+struct Camera {
+    name: String,
+    streaming_interface: u8,
+    /// bcdUVC, which sets the probe control's length
+    version: u16,
+    formats: Vec<Format>,
+    selection: Selection,
+    open: bool,
+    streaming: bool,
+    /// The largest frame the camera said it would send
+    max_frame_size: usize,
+    urbs: [Option<UrbId>; URBS],
+    /// The frame being put together, its frame ID, and whether any of it
+    /// was damaged
+    assembling: Vec<u8>,
+    fid: Option<u8>,
+    damaged: bool,
+    frames: VecDeque<VideoFrame>,
+    captured: u64,
+    dropped: u64,
+    device: Arc<RwLock<dyn CaptureDevice>>,
+}
+
+// Cameras by USB address
+static CAMERAS: Mutex<BTreeMap<u8, Camera>> = Mutex::new(BTreeMap::new());
+
+/// A camera, for listing
+#[derive(Debug, Clone)]
+pub struct CameraInfo {
+    pub address: u8,
+    pub name: String,
+    pub version: u16,
+    pub streaming: bool,
+    pub format: MediaFormat,
+    pub captured: u64,
+    pub dropped: u64,
+}
+
+pub fn cameras() -> Vec<CameraInfo> {
+    CAMERAS
+        .lock()
+        .iter()
+        .map(|(&address, camera)| CameraInfo {
+            address,
+            name: camera.name.clone(),
+            version: camera.version,
+            streaming: camera.streaming,
+            format: camera.selection.media_format(),
+            captured: camera.captured,
+            dropped: camera.dropped,
+        })
+        .collect()
+}
+
+fn media_format(encoding: Encoding, frame: &FrameSize) -> MediaFormat {
+    MediaFormat {
+        media_type: MediaType::Video,
+        codec: String::from(if encoding == Encoding::Mjpeg { "mjpeg" } else { "raw" }),
+        bitrate: None,
+        sample_rate: None,
+        channels: None,
+        width: Some(frame.width as u32),
+        height: Some(frame.height as u32),
+        framerate: (frame.interval != 0).then(|| 10_000_000.0 / frame.interval as f32),
+        pixel_format: Some(encoding.pixel_format()),
+        audio_format: None,
+        extra_data: Vec::new(),
+    }
+}
+
+// The class descriptors that follow an interface, one at a time
+fn descriptors(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut at = 0;
+    core::iter::from_fn(move || {
+        let length = *data.get(at)? as usize;
+        if length < 3 || at + length > data.len() {
+            return None;
+        }
+        let descriptor = &data[at..at + length];
+        at += length;
+        Some(descriptor)
+    })
+}
+
+fn le16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn uvc_version(control: &InterfaceInfo) -> u16 {
+    descriptors(&control.class_descriptors)
+        .find(|d| d[1] == CS_INTERFACE && d[2] == VC_HEADER && d.len() >= 5)
+        .map_or(0x0100, |d| le16(d, 3))
+}
+
+// The formats the streaming interface lists, less those that are not
+// handled; a frame descriptor belongs to the format before it
+fn parse_formats(streaming: &InterfaceInfo) -> Vec<Format> {
+    let mut formats: Vec<Format> = Vec::new();
+    let mut taken = false;
+    for d in descriptors(&streaming.class_descriptors).filter(|d| d[1] == CS_INTERFACE) {
+        match d[2] {
+            VS_FORMAT_UNCOMPRESSED | VS_FORMAT_MJPEG if d.len() >= 5 => {
+                let encoding = match d[2] {
+                    VS_FORMAT_MJPEG => Some(Encoding::Mjpeg),
+                    _ if d.len() >= 21 && &d[5..9] == b"YUY2" => Some(Encoding::Yuyv),
+                    _ if d.len() >= 21 && &d[5..9] == b"NV12" => Some(Encoding::Nv12),
+                    _ => None,
+                };
+                taken = encoding.is_some();
+                if let Some(encoding) = encoding {
+                    formats.push(Format { index: d[3], encoding, frames: Vec::new() });
+                }
+            }
+            VS_FRAME_UNCOMPRESSED | VS_FRAME_MJPEG if taken && d.len() >= 26 => {
+                if let Some(format) = formats.last_mut() {
+                    let frame = FrameSize { index: d[3], width: le16(d, 5), height: le16(d, 7), interval: le32(d, 21) };
+                    format.frames.push(frame);
+                }
+            }
+            _ => {}
+        }
+    }
+    formats.retain(|format| !format.frames.is_empty());
+    formats
+}
+
+// The first format's largest frame that is not larger than the default
+// size, or its smallest
+fn default_selection(formats: &[Format]) -> Option<Selection> {
+    let format = formats.first()?;
+    let area = |frame: &&FrameSize| frame.width as u32 * frame.height as u32;
+    let frame = format
+        .frames
+        .iter()
+        .filter(|frame| frame.width <= DEFAULT_WIDTH && frame.height <= DEFAULT_HEIGHT)
+        .max_by_key(area)
+        .or_else(|| format.frames.iter().min_by_key(area))?;
+    Some(Selection { format: format.index, encoding: format.encoding, frame: *frame })
+}
+
+// The probe and commit controls' length, by bcdUVC
+fn probe_length(version: u16) -> usize {
+    match version {
+        0..=0x0100 => 26,
+        0x0101..=0x0149 => 34,
+        _ => 48,
+    }
+}
+
+fn streaming_control(
+    address: u8,
+    interface: u8,
+    control: u16,
+    request: u8,
+    data: &mut [u8],
+) -> Result<usize, &'static str> {
+    let setup = DeviceRequest {
+        // Class, interface; to the device for SET_CUR, from it for GET_CUR
+        request_type: if request == SET_CUR { 0x21 } else { 0xA1 },
+        request,
+        value: control << 8,
+        index: interface as u16,
+        length: data.len() as u16,
+    };
+    crate::usb::control_transfer(address, &setup, Some(data))
+}
+
+// Agree on the selection with the camera: offer it, read back what the
+// camera makes of it and commit that. Returns the largest frame and
+// payload the camera will send.
+fn negotiate(address: u8, interface: u8, version: u16, selection: &Selection) -> Result<(usize, usize), &'static str> {
+    let mut probe = vec![0u8; probe_length(version)];
+    // bmHint: keep the frame interval
+    probe[0] = 0x01;
+    probe[2] = selection.format;
+    probe[3] = selection.frame.index;
+    probe[4..8].copy_from_slice(&selection.frame.interval.to_le_bytes());
+    streaming_control(address, interface, VS_PROBE_CONTROL, SET_CUR, &mut probe)?;
+    let length = streaming_control(address, interface, VS_PROBE_CONTROL, GET_CUR, &mut probe)?;
+    if length < 26 {
+        return Err("probe control too short");
+    }
+    if probe[2] != selection.format || probe[3] != selection.frame.index {
+        return Err("camera chose another format");
+    }
+    streaming_control(address, interface, VS_COMMIT_CONTROL, SET_CUR, &mut probe)?;
+    Ok((le32(&probe, 18) as usize, le32(&probe, 22) as usize))
+}
+
+// The transport for a payload size: the bulk endpoint if there is one,
+// or else the smallest alternate setting whose isochronous endpoint
+// carries the payload, or the largest there is
+fn transport(usb: &super::UsbDevice, interface: u8, payload: usize) -> Option<Transport> {
+    let alternates = || usb.interfaces.iter().filter(move |i| i.number == interface);
+    if let Some(endpoint) = alternates().find_map(|i| i.bulk_in()) {
+        let size = if payload == 0 { endpoint.max_packet_size as usize * 32 } else { payload };
+        return Some(Transport::Bulk { endpoint: endpoint.address, size });
+    }
+    let mut settings: Vec<(u8, u8, usize)> = alternates()
+        .filter_map(|i| i.isochronous_in().map(|e| (i.alternate, e.address, e.bytes_per_interval())))
+        .filter(|&(_, _, size)| size > 0)
+        .collect();
+    settings.sort_by_key(|&(_, _, size)| size);
+    let (alternate, endpoint, packet_size) =
+        settings.iter().find(|&&(_, _, size)| size >= payload).or(settings.last()).copied()?;
+    Some(Transport::Isochronous { alternate, endpoint, packet_size })
+}
+
+// Close the frame being put together, keeping it if it came whole
+fn end_frame(camera: &mut Camera) {
+    let data = core::mem::take(&mut camera.assembling);
+    if data.is_empty() {
+        camera.damaged = false;
+        return;
+    }
+    let frame = camera.selection.frame;
+    let whole = !camera.damaged
+        && match camera.selection.encoding.frame_size(frame.width, frame.height) {
+            Some(size) => data.len() == size,
+            None => data.starts_with(&[0xFF, 0xD8]),
+        };
+    camera.damaged = false;
+    if !whole {
+        camera.dropped += 1;
+        return;
+    }
+    if camera.frames.len() == QUEUE {
+        camera.frames.pop_front();
+        camera.dropped += 1;
+    }
+    camera.captured += 1;
+    camera.frames.push_back(VideoFrame {
+        data,
+        width: frame.width as u32,
+        height: frame.height as u32,
+        pixel_format: camera.selection.encoding.pixel_format(),
+        timestamp: crate::time::clocksource::now_ns(),
+    });
+}
+
+// One payload: its header, then a piece of the current frame
+fn payload(camera: &mut Camera, data: &[u8]) {
+    let Some(&length) = data.first() else {
+        return;
+    };
+    let length = length as usize;
+    if length < 2 || length > data.len() {
+        return;
+    }
+    let flags = data[1];
+    let fid = flags & HEADER_FID;
+    if camera.fid.is_some_and(|last| last != fid) {
+        end_frame(camera);
+    }
+    camera.fid = Some(fid);
+    if flags & HEADER_ERR != 0 {
+        camera.damaged = true;
+    }
+    if camera.assembling.len() + data.len() - length > camera.max_frame_size {
+        camera.damaged = true;
+    } else {
+        camera.assembling.extend_from_slice(&data[length..]);
+    }
+    if flags & HEADER_EOF != 0 {
+        end_frame(camera);
+    }
+}
+
+fn stream_urb(address: u8, transport: Transport) -> Urb {
+    match transport {
+        Transport::Isochronous { endpoint, packet_size, .. } => {
+            Urb::isochronous(address, endpoint, PACKETS, packet_size)
+        }
+        Transport::Bulk { endpoint, size } => Urb::bulk(address, endpoint, vec![0u8; size]),
+    }
+}
+
+// Keep a transfer waiting in `slot` while the camera streams. Each one
+// that comes back is taken apart into payloads and sent again; if one
+// fails, streaming stops.
+fn submit_stream_urb(address: u8, transport: Transport, slot: usize) -> Result<UrbId, &'static str> {
+    urb::submit(stream_urb(address, transport).on_complete(move |urb| match urb.status {
+        UrbStatus::Completed => {
+            {
+                let mut cameras = CAMERAS.lock();
+                let Some(camera) = cameras.get_mut(&address).filter(|camera| camera.streaming) else {
+                    return;
+                };
+                match transport {
+                    Transport::Isochronous { .. } => urb.packet_data().for_each(|data| payload(camera, data)),
+                    Transport::Bulk { .. } => payload(camera, urb.data()),
+                }
+            }
+            let next = submit_stream_urb(address, transport, slot).ok();
+            if let Some(camera) = CAMERAS.lock().get_mut(&address) {
+                camera.urbs[slot] = next;
+            }
+        }
+        UrbStatus::Cancelled => {}
+        status => {
+            serial_println!("uvc: streaming from device {} stopped: {:?}", address, status);
+            stop(address);
+        }
+    }))
+}
+
+// Negotiate the selection and start the transfers
+fn start(address: u8) -> Result<(), MediaError> {
+    let (interface, version, selection) = {
+        let cameras = CAMERAS.lock();
+        let camera = cameras.get(&address).ok_or(MediaError::InvalidState)?;
+        if !camera.open || camera.streaming {
+            return Err(MediaError::InvalidState);
+        }
+        (camera.streaming_interface, camera.version, camera.selection)
+    };
+    let usb = crate::usb::device(address).ok_or(MediaError::CaptureError)?;
+    let fail = |e: &'static str| {
+        serial_println!("uvc: cannot start device {}: {}", address, e);
+        MediaError::CaptureError
+    };
+    crate::usb::set_interface(address, interface, 0).map_err(fail)?;
+    let (max_frame_size, payload_size) = negotiate(address, interface, version, &selection).map_err(fail)?;
+    let transport = transport(&usb, interface, payload_size).ok_or_else(|| fail("no streaming endpoint"))?;
+    if let Transport::Isochronous { alternate, .. } = transport {
+        crate::usb::set_interface(address, interface, alternate).map_err(fail)?;
+    }
+    let frame = selection.frame;
+    let max_frame_size = match selection.encoding.frame_size(frame.width, frame.height) {
+        Some(size) => size,
+        None if max_frame_size > 0 => max_frame_size,
+        None => frame.width as usize * frame.height as usize * 2,
+    };
+    {
+        let mut cameras = CAMERAS.lock();
+        let camera = cameras.get_mut(&address).ok_or(MediaError::InvalidState)?;
+        camera.streaming = true;
+        camera.max_frame_size = max_frame_size;
+        camera.assembling.clear();
+        camera.fid = None;
+        camera.damaged = false;
+        camera.frames.clear();
+    }
+    for slot in 0..URBS {
+        match submit_stream_urb(address, transport, slot) {
+            Ok(id) => {
+                if let Some(camera) = CAMERAS.lock().get_mut(&address) {
+                    camera.urbs[slot] = Some(id);
+                }
+            }
+            Err(e) => {
+                stop(address);
+                return Err(fail(e));
+            }
+        }
+    }
+    serial_println!(
+        "uvc: device {} streaming {}x{} {:?} over {:?}",
+        address,
+        frame.width,
+        frame.height,
+        selection.encoding,
+        transport
+    );
+    Ok(())
+}
+
+// Stop the transfers and put the streaming interface back at zero
+// bandwidth
+fn stop(address: u8) {
+    let (interface, urbs) = {
+        let mut cameras = CAMERAS.lock();
+        let Some(camera) = cameras.get_mut(&address).filter(|camera| camera.streaming) else {
+            return;
+        };
+        camera.streaming = false;
+        camera.assembling.clear();
+        (camera.streaming_interface, core::mem::take(&mut camera.urbs))
+    };
+    for id in urbs.into_iter().flatten() {
+        urb::cancel(id);
+    }
+    // An unplugged camera cannot be told
+    let _ = crate::usb::set_interface(address, interface, 0);
+}
+
+/// Wait for a camera's next frame, serving USB transfers meanwhile, for up
+/// to `timeout_ms` milliseconds
+pub fn next_frame(device: &Arc<RwLock<dyn CaptureDevice>>, timeout_ms: u64) -> Result<VideoFrame, MediaError> {
+    let deadline = crate::time::clocksource::now_ns() + timeout_ms * 1_000_000;
+    loop {
+        match device.write().capture_frame() {
+            Ok(CapturedFrame::Video(frame)) => return Ok(frame),
+            Ok(CapturedFrame::Audio(_)) => return Err(MediaError::InvalidFormat),
+            Err(MediaError::BufferUnderflow) => {}
+            Err(e) => return Err(e),
+        }
+        if crate::time::clocksource::now_ns() >= deadline {
+            return Err(MediaError::Timeout);
+        }
+        urb::poll();
+        core::hint::spin_loop();
+    }
+}
+
+/// A YUYV or NV12 frame as 24-bit RGB, red first
+pub fn rgb24(frame: &VideoFrame) -> Option<Vec<u8>> {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let yuv = |x: usize, y: usize| -> (i32, i32, i32) {
+        match frame.pixel_format {
+            PixelFormat::YUYV => {
+                let at = (y * width + (x & !1)) * 2;
+                (frame.data[at + (x & 1) * 2] as i32, frame.data[at + 1] as i32, frame.data[at + 3] as i32)
+            }
+            _ => {
+                let chroma = width * height + (y / 2) * width + (x & !1);
+                (frame.data[y * width + x] as i32, frame.data[chroma] as i32, frame.data[chroma + 1] as i32)
+            }
+        }
+    };
+    let size = match frame.pixel_format {
+        PixelFormat::YUYV => width * height * 2,
+        PixelFormat::NV12 => width * height * 3 / 2,
+        _ => return None,
+    };
+    if frame.data.len() < size {
+        return None;
+    }
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            // BT.601, studio range
+            let (luma, u, v) = yuv(x, y);
+            let (c, d, e) = (298 * (luma - 16), u - 128, v - 128);
+            let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+            rgb.extend_from_slice(&[clamp(c + 409 * e), clamp(c - 100 * d - 208 * e), clamp(c + 516 * d)]);
+        }
+    }
+    Some(rgb)
+}
+
+/// A camera as the capture manager sees it; the state is kept by address
+pub struct UvcCamera {
+    address: u8,
+    name: String,
+}
+
+impl UvcCamera {
+    fn with<T>(&self, f: impl FnOnce(&mut Camera) -> Result<T, MediaError>) -> Result<T, MediaError> {
+        CAMERAS.lock().get_mut(&self.address).map_or(Err(MediaError::InvalidState), f)
+    }
+}
+
+impl CaptureDevice for UvcCamera {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_device_type(&self) -> DeviceType {
+        DeviceType::Camera
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        let formats = CAMERAS.lock().get(&self.address).map(|camera| camera.formats.clone()).unwrap_or_default();
+        let sizes =
+            || formats.iter().flat_map(|format| format.frames.iter()).map(|f| (f.width as u32, f.height as u32));
+        let mut framerates: Vec<f32> = Vec::new();
+        let mut supported_formats = Vec::new();
+        for format in &formats {
+            for frame in &format.frames {
+                let media = media_format(format.encoding, frame);
+                if let Some(rate) = media.framerate.filter(|rate| !framerates.contains(rate)) {
+                    framerates.push(rate);
+                }
+                supported_formats.push(media);
+            }
+        }
+        DeviceCapabilities {
+            supported_formats,
+            min_resolution: sizes().min_by_key(|&(w, h)| w * h),
+            max_resolution: sizes().max_by_key(|&(w, h)| w * h),
+            supported_framerates: framerates,
+            supported_sample_rates: Vec::new(),
+            supported_channels: Vec::new(),
+            has_autofocus: false,
+            has_zoom: false,
+            has_flash: false,
+        }
+    }
+
+    fn open(&mut self) -> Result<(), MediaError> {
+        self.with(|camera| {
+            if camera.open {
+                return Err(MediaError::InvalidState);
+            }
+            camera.open = true;
+            Ok(())
+        })
+    }
+
+    fn close(&mut self) -> Result<(), MediaError> {
+        self.with(|camera| {
+            if !camera.open {
+                return Err(MediaError::InvalidState);
+            }
+            camera.open = false;
+            Ok(())
+        })?;
+        stop(self.address);
+        Ok(())
+    }
+
+    fn start_capture(&mut self) -> Result<(), MediaError> {
+        start(self.address)
+    }
+
+    fn stop_capture(&mut self) -> Result<(), MediaError> {
+        if !self.with(|camera| Ok(camera.streaming))? {
+            return Err(MediaError::InvalidState);
+        }
+        stop(self.address);
+        Ok(())
+    }
+
+    /// The oldest frame waiting; BufferUnderflow if none has come yet
+    fn capture_frame(&mut self) -> Result<CapturedFrame, MediaError> {
+        self.with(|camera| {
+            if !camera.streaming {
+                return Err(MediaError::InvalidState);
+            }
+            camera.frames.pop_front().map(CapturedFrame::Video).ok_or(MediaError::BufferUnderflow)
+        })
+    }
+
+    /// Pick the listed format and frame size matching the pixel format and
+    /// size asked for, and the nearest frame rate if one is given
+    fn set_format(&mut self, format: &MediaFormat) -> Result<(), MediaError> {
+        self.with(|camera| {
+            if camera.streaming {
+                return Err(MediaError::InvalidState);
+            }
+            let chosen = camera
+                .formats
+                .iter()
+                .filter(|f| format.pixel_format.map_or(true, |pixels| pixels == f.encoding.pixel_format()))
+                .flat_map(|f| f.frames.iter().map(move |frame| (f, frame)))
+                .filter(|(_, frame)| format.width.map_or(true, |width| width == frame.width as u32))
+                .filter(|(_, frame)| format.height.map_or(true, |height| height == frame.height as u32))
+                .min_by_key(|(_, frame)| match format.framerate {
+                    Some(rate) if rate > 0.0 => (frame.interval as i64 - (10_000_000.0 / rate) as i64).unsigned_abs(),
+                    _ => 0,
+                });
+            let (f, frame) = chosen.ok_or(MediaError::InvalidFormat)?;
+            camera.selection = Selection { format: f.index, encoding: f.encoding, frame: *frame };
+            Ok(())
+        })
+    }
+
+    fn get_format(&self) -> MediaFormat {
+        match CAMERAS.lock().get(&self.address) {
+            Some(camera) => camera.selection.media_format(),
+            None => media_format(Encoding::Yuyv, &FrameSize { index: 0, width: 0, height: 0, interval: 0 }),
+        }
+    }
+}
+
+// Let the camera at `address` go
+fn detach(address: u8) {
+    stop(address);
+    if let Some(camera) = CAMERAS.lock().remove(&address) {
+        CAPTURE_MANAGER.unregister_device(&camera.device);
+        serial_println!("uvc: {} removed", camera.name);
+    }
+}
+
+pub static USB_DRIVER: UvcDriver = UvcDriver;
+
+pub struct UvcDriver;
+
+impl Driver for UvcDriver {
+    fn name(&self) -> &'static str {
+        "uvc"
+    }
+
+    fn bus(&self) -> BusType {
+        BusType::Usb
+    }
+
+    fn id_table(&self) -> &'static [Match] {
+        &[
+            // Most webcams: an interface association of video interfaces
+            Match::UsbClass { class: USB_CLASS_MISC, subclass: Some(MISC_COMMON), protocol: Some(MISC_IAD) },
+            Match::UsbClass { class: USB_CLASS_VIDEO, subclass: None, protocol: None },
+        ]
+    }
+
+    fn probe(&self, device: &Device) -> Result<(), ProbeError> {
+        let Ident::Usb { address, .. } = device.ident else {
+            return Err(ProbeError::NoDevice);
+        };
+        let usb = crate::usb::device(address).ok_or(ProbeError::NoDevice)?;
+        let video = |subclass: u8| {
+            usb.interfaces.iter().find(|i| i.class == USB_CLASS_VIDEO && i.subclass == subclass && i.alternate == 0)
+        };
+        let (Some(control), Some(streaming)) = (video(SC_VIDEOCONTROL), video(SC_VIDEOSTREAMING)) else {
+            return Err(ProbeError::NoDevice);
+        };
+        let formats = parse_formats(streaming);
+        let selection = default_selection(&formats).ok_or(ProbeError::Failed("no usable video format"))?;
+        let name = match usb.product.as_str() {
+            "" => format!("USB camera {}", address),
+            product => String::from(product),
+        };
+        let version = uvc_version(control);
+        // Leave the camera at zero bandwidth until it is started
+        crate::usb::set_interface(address, streaming.number, 0).map_err(ProbeError::Failed)?;
+        let device: Arc<RwLock<dyn CaptureDevice>> = Arc::new(RwLock::new(UvcCamera { address, name: name.clone() }));
+        serial_println!(
+            "uvc: {} (UVC {:x}.{:02x}), {} formats, {}x{} by default",
+            name,
+            version >> 8,
+            version & 0xFF,
+            formats.len(),
+            selection.frame.width,
+            selection.frame.height
+        );
+        let camera = Camera {
+            name,
+            streaming_interface: streaming.number,
+            version,
+            formats,
+            selection,
+            open: false,
+            streaming: false,
+            max_frame_size: 0,
+            urbs: [None; URBS],
+            assembling: Vec::new(),
+            fid: None,
+            damaged: false,
+            frames: VecDeque::new(),
+            captured: 0,
+            dropped: 0,
+            device: device.clone(),
+        };
+        CAMERAS.lock().insert(address, camera);
+        CAPTURE_MANAGER.register_device(device);
+        Ok(())
+    }
+
+    fn remove(&self, device: &Device) {
+        if let Ident::Usb { address, .. } = device.ident {
+            detach(address);
+        }
+    }
+}
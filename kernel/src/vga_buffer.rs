@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use volatile::Volatile;
 use core::fmt;
 use lazy_static::lazy_static;
@@ -42,6 +43,9 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+// Shown for what the font has no glyph for, a small square
+const UNKNOWN: u8 = 0xFE;
+
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
@@ -54,12 +58,18 @@ pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    // A UTF-8 sequence begun by earlier bytes: its bits so far and how
+    // many bytes are still to come
+    pending: (u32, u8),
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // Back a column, and back to the first, without erasing
+            b'\x08' => self.column_position = self.column_position.saturating_sub(1),
+            b'\r' => self.column_position = 0,
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -80,11 +90,39 @@ impl Writer {
 
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+            self.write_utf8(byte);
+        }
+    }
+
+    /// Take a byte of UTF-8 text. Each character is shown as its code page
+    /// 437 glyph, the font's, or else as its fallback's, without accents;
+    /// anything else, and bytes that are not UTF-8, show as a square.
+    pub fn write_utf8(&mut self, byte: u8) {
+        let (bits, remaining) = self.pending;
+        if remaining > 0 {
+            if byte & 0xC0 == 0x80 {
+                let bits = bits << 6 | (byte & 0x3F) as u32;
+                self.pending = (bits, remaining - 1);
+                if remaining == 1 {
+                    self.write_char(char::from_u32(bits).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                return;
             }
+            // A sequence cut short
+            self.pending = (0, 0);
+            self.write_byte(UNKNOWN);
         }
+        match byte {
+            0x20..=0x7e | b'\n' | b'\x08' | b'\r' => self.write_byte(byte),
+            0xC2..=0xDF => self.pending = ((byte & 0x1F) as u32, 1),
+            0xE0..=0xEF => self.pending = ((byte & 0x0F) as u32, 2),
+            0xF0..=0xF4 => self.pending = ((byte & 0x07) as u32, 3),
+            _ => self.write_byte(UNKNOWN),
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        self.write_byte(text_byte(c));
     }
 
     fn new_line(&mut self) {
@@ -114,6 +152,22 @@ impl Writer {
         }
         self.column_position = 0;
     }
+    
+    /// Read a single cell as its character and raw attribute byte
+    pub fn cell(&self, row: usize, col: usize) -> (u8, u8) {
+        let cell = self.buffer.chars[row][col].read();
+        (cell.ascii_character, cell.color_code.0)
+    }
+    
+    /// Write a single cell with a raw VGA attribute byte
+    pub fn put_cell(&mut self, row: usize, col: usize, byte: u8, attribute: u8) {
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character: byte,
+                color_code: ColorCode(attribute),
+            });
+        }
+    }
 }
 
 impl fmt::Write for Writer {
@@ -128,6 +182,7 @@ lazy_static! {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        pending: (0, 0),
     });
 }
 
@@ -152,10 +207,143 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Write raw bytes unless the screen is in use, for output that must not
+/// wait on it; false if it was busy
+pub fn try_write_bytes(bytes: &[u8]) -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            for &byte in bytes {
+                writer.write_utf8(byte);
+            }
+            true
+        }
+        None => false,
+    })
+}
+
+/// The byte that shows `c` on the text screen: its code page 437 glyph,
+/// or its fallback's, or a square. Control characters are squares too.
+pub fn text_byte(c: char) -> u8 {
+    use crate::fs::unicode::to_cp437;
+    use crate::graphics::font::fallback;
+
+    let byte = to_cp437(c).or_else(|| fallback(c).and_then(to_cp437));
+    byte.filter(|&byte| byte >= 0x20 && byte != 0x7F).unwrap_or(UNKNOWN)
+}
+
+pub const TEXT_WIDTH: usize = BUFFER_WIDTH;
+pub const TEXT_HEIGHT: usize = BUFFER_HEIGHT;
+
+/// Copy a block of (character, attribute) cells to the text-mode framebuffer
+pub fn blit_cells(cells: &[(u8, u8)], width: usize) {
+    use x86_64::instructions::interrupts;
+    
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for (index, &(byte, attribute)) in cells.iter().enumerate() {
+            writer.put_cell(index / width, index % width, byte, attribute);
+        }
+    });
+}
+
+/// Copy the text-mode framebuffer out, as blit_cells takes it, to put it
+/// back later
+pub fn read_cells() -> Vec<(u8, u8)> {
+    use x86_64::instructions::interrupts;
+    
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        (0..BUFFER_HEIGHT * BUFFER_WIDTH).map(|index| writer.cell(index / BUFFER_WIDTH, index % BUFFER_WIDTH)).collect()
+    })
+}
+
+/// Move and show or hide the hardware text cursor
+pub fn set_cursor(row: usize, col: usize, visible: bool) {
+    use x86_64::instructions::port::Port;
+    
+    let mut index: Port<u8> = Port::new(0x3D4);
+    let mut data: Port<u8> = Port::new(0x3D5);
+    let position = (row * BUFFER_WIDTH + col) as u16;
+    
+    unsafe {
+        // Cursor start register: bit 5 disables the cursor
+        index.write(0x0A);
+        let start = data.read();
+        index.write(0x0A);
+        data.write(if visible { start & !0x20 } else { start | 0x20 });
+        
+        index.write(0x0F);
+        data.write((position & 0xFF) as u8);
+        index.write(0x0E);
+        data.write((position >> 8) as u8);
+    }
+}
+
+/// Where the hardware cursor is, as (row, column), unless it is hidden
+pub fn cursor() -> Option<(usize, usize)> {
+    use x86_64::instructions::port::Port;
+    
+    let mut index: Port<u8> = Port::new(0x3D4);
+    let mut data: Port<u8> = Port::new(0x3D5);
+    
+    let (start, high, low) = unsafe {
+        index.write(0x0A);
+        let start = data.read();
+        index.write(0x0E);
+        let high = data.read();
+        index.write(0x0F);
+        (start, high, data.read())
+    };
+    let position = ((high as usize) << 8 | low as usize).min(BUFFER_HEIGHT * BUFFER_WIDTH - 1);
+    (start & 0x20 == 0).then_some((position / BUFFER_WIDTH, position % BUFFER_WIDTH))
+}
+
+/// Put the hardware cursor where the next character printed will go
+pub fn follow_cursor() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let column = WRITER.lock().column_position.min(BUFFER_WIDTH - 1);
+        set_cursor(BUFFER_HEIGHT - 1, column, true);
+    });
+}
+
 pub fn clear_screen() {
     use x86_64::instructions::interrupts;
     
     interrupts::without_interrupts(|| {
         WRITER.lock().clear_screen();
     });
+}
+
+/// The VGA attribute byte new text is written in
+pub fn attribute() -> u8 {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().color_code.0)
+}
+
+/// Write new text in `attribute` from now on
+pub fn set_attribute(attribute: u8) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().color_code = ColorCode(attribute));
+}
+
+/// Write bytes of UTF-8 text, each in its own attribute, leaving the
+/// attribute for later text as it was
+pub fn write_attributed(text: &[(u8, u8)]) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color_code = writer.color_code;
+        for &(byte, attribute) in text {
+            writer.color_code = ColorCode(attribute);
+            writer.write_utf8(byte);
+        }
+        writer.color_code = color_code;
+    });
 }
\ No newline at end of file
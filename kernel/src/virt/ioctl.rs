@@ -0,0 +1,359 @@
+//! /dev/kvm-style handles and ioctls
+//!
+//! Request numbers and the register, segment and memory-region structures
+//! follow KVM's, so a VMM written against KVM needs few changes:
+//!
+//! - There is no mmap of the vCPU: KVM_RUN takes a pointer to a `RunState`,
+//!   which the VMM fills with the data of a pending IN and the kernel fills
+//!   with the exit.
+//! - MMIO exits carry only the address and direction. The VMM decodes the
+//!   instruction at rip itself and advances rip with KVM_SET_REGS.
+//! - Memory behind a slot must stay mapped and populated while the VM
+//!   exists; pages are looked up when the guest first touches them.
+//! - There is no in-kernel interrupt controller; KVM_INTERRUPT injects
+//!   vectors directly.
+//!
+//! Handles belong to the process that opened them and are released when
+//! it exits.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::{Exit, MemorySlot, Regs, Sregs, Vcpu, VirtError, Vm};
+use crate::memory::userspace::validate_user_buffer;
+use crate::sync::Mutex;
+use crate::syscall::{EACCES, EBADF, EBUSY, EFAULT, EINVAL, EIO, EMFILE, ENODEV, ENOENT, ENOMEM, ENOTTY};
+
+pub const DEVICE_PATH: &str = "/dev/kvm";
+
+pub const KVM_API_VERSION: usize = 12;
+
+pub const KVM_GET_API_VERSION: usize = 0xae00;
+pub const KVM_CREATE_VM: usize = 0xae01;
+pub const KVM_CHECK_EXTENSION: usize = 0xae03;
+pub const KVM_CREATE_VCPU: usize = 0xae41;
+pub const KVM_SET_USER_MEMORY_REGION: usize = 0x4020_ae46;
+pub const KVM_RUN: usize = 0xae80;
+pub const KVM_GET_REGS: usize = 0x8090_ae81;
+pub const KVM_SET_REGS: usize = 0x4090_ae82;
+pub const KVM_GET_SREGS: usize = 0x8138_ae83;
+pub const KVM_SET_SREGS: usize = 0x4138_ae84;
+pub const KVM_INTERRUPT: usize = 0x4004_ae86;
+
+pub const KVM_CAP_USER_MEMORY: usize = 3;
+pub const KVM_CAP_NR_VCPUS: usize = 9;
+pub const KVM_CAP_NR_MEMSLOTS: usize = 10;
+pub const KVM_CAP_READONLY_MEM: usize = 81;
+
+pub const KVM_EXIT_UNKNOWN: u32 = 0;
+pub const KVM_EXIT_IO: u32 = 2;
+pub const KVM_EXIT_HLT: u32 = 5;
+pub const KVM_EXIT_MMIO: u32 = 6;
+pub const KVM_EXIT_SHUTDOWN: u32 = 8;
+pub const KVM_EXIT_FAIL_ENTRY: u32 = 9;
+pub const KVM_EXIT_INTERNAL_ERROR: u32 = 17;
+
+pub const KVM_EXIT_IO_IN: u8 = 0;
+pub const KVM_EXIT_IO_OUT: u8 = 1;
+
+pub const KVM_MEM_READONLY: u32 = 1 << 1;
+
+pub const MAX_VCPUS: u32 = 64;
+pub const MAX_MEMORY_SLOTS: u32 = 32;
+
+// Per-process limit on open handles
+const MAX_HANDLES: usize = 256;
+
+// 0, 1 and 2 are the console
+const FIRST_FD: usize = 3;
+
+/// `struct kvm_userspace_memory_region`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct UserMemoryRegion {
+    pub slot: u32,
+    pub flags: u32,
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+}
+
+/// KVM_RUN's argument, standing in for the mmap'd `struct kvm_run`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunState {
+    /// KVM_EXIT_*
+    pub exit_reason: u32,
+    pub ready_for_interrupt_injection: u8,
+    pub if_flag: u8,
+    /// KVM_EXIT_IO_IN or KVM_EXIT_IO_OUT
+    pub io_direction: u8,
+    /// Access size in bytes
+    pub io_size: u8,
+    pub io_port: u16,
+    pub mmio_is_write: u8,
+    pub padding: u8,
+    /// OUT data on exit; on entry, the data for the IN that last exited
+    pub io_data: u32,
+    pub mmio_phys_addr: u64,
+    /// Hardware exit reason for KVM_EXIT_FAIL_ENTRY and KVM_EXIT_UNKNOWN
+    pub hardware_exit_reason: u64,
+}
+
+#[derive(Clone)]
+enum Handle {
+    System,
+    Vm(Arc<Vm>),
+    Vcpu(Arc<Vm>, Arc<Mutex<Vcpu>>),
+}
+
+struct Handles {
+    // By (process, fd)
+    open: BTreeMap<(u32, usize), Handle>,
+    next_vm_id: u32,
+}
+
+impl Handles {
+    const fn new() -> Self {
+        Handles {
+            open: BTreeMap::new(),
+            next_vm_id: 1,
+        }
+    }
+
+    fn insert(&mut self, pid: u32, handle: Handle) -> Result<usize, usize> {
+        let used: Vec<usize> = self.open.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+        if used.len() >= MAX_HANDLES {
+            return Err(EMFILE);
+        }
+        let fd = (FIRST_FD..).find(|fd| !used.contains(fd)).unwrap_or(FIRST_FD);
+        self.open.insert((pid, fd), handle);
+        Ok(fd)
+    }
+}
+
+static HANDLES: Mutex<Handles> = Mutex::new(Handles::new());
+
+fn current_pid() -> u32 {
+    crate::process::PROCESS_MANAGER.lock().current_process.map_or(0, |pid| pid.0)
+}
+
+fn errno(error: VirtError) -> usize {
+    match error {
+        VirtError::NotSupported | VirtError::DisabledByFirmware => ENODEV,
+        VirtError::EnableFailed => EIO,
+        VirtError::OutOfMemory => ENOMEM,
+        VirtError::InvalidArgument => EINVAL,
+        VirtError::BadAddress => EFAULT,
+        VirtError::NotFound => ENOENT,
+        VirtError::Busy => EBUSY,
+    }
+}
+
+fn read_user<T: Copy>(addr: usize) -> Result<T, usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { (addr as *const T).read_unaligned() })
+}
+
+fn write_user<T: Copy>(addr: usize, value: &T) -> Result<(), usize> {
+    let user_addr = VirtAddr::try_new(addr as u64).map_err(|_| EFAULT)?;
+    if !validate_user_buffer(user_addr, core::mem::size_of::<T>()) {
+        return Err(EFAULT);
+    }
+    unsafe { (addr as *mut T).write_unaligned(*value) };
+    Ok(())
+}
+
+/// Open /dev/kvm. Running guests is reserved for administrators.
+pub fn open() -> Result<usize, usize> {
+    if super::backend().is_none() {
+        return Err(ENODEV);
+    }
+    if !crate::security::accounts::caller_is_admin() {
+        return Err(EACCES);
+    }
+    HANDLES.lock().insert(current_pid(), Handle::System)
+}
+
+/// Close a handle; false if `fd` is not one of ours
+pub fn close(fd: usize) -> bool {
+    let pid = current_pid();
+    let handle = HANDLES.lock().open.remove(&(pid, fd));
+    // Dropped here, outside the table lock
+    handle.is_some()
+}
+
+/// Drop every handle of an exiting process
+pub fn release_process(pid: u32) {
+    let released: Vec<Handle> = {
+        let mut handles = HANDLES.lock();
+        let fds: Vec<usize> = handles.open.range((pid, 0)..=(pid, usize::MAX)).map(|(&(_, fd), _)| fd).collect();
+        fds.into_iter().filter_map(|fd| handles.open.remove(&(pid, fd))).collect()
+    };
+    drop(released);
+}
+
+pub fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, usize> {
+    let pid = current_pid();
+    let handle = HANDLES.lock().open.get(&(pid, fd)).cloned().ok_or(EBADF)?;
+
+    match handle {
+        Handle::System => system_ioctl(pid, request, arg),
+        Handle::Vm(vm) => vm_ioctl(pid, &vm, request, arg),
+        Handle::Vcpu(vm, vcpu) => vcpu_ioctl(&vm, &vcpu, request, arg),
+    }
+}
+
+fn system_ioctl(pid: u32, request: usize, arg: usize) -> Result<usize, usize> {
+    match request {
+        KVM_GET_API_VERSION => Ok(KVM_API_VERSION),
+        KVM_CHECK_EXTENSION => Ok(match arg {
+            KVM_CAP_USER_MEMORY | KVM_CAP_READONLY_MEM => 1,
+            KVM_CAP_NR_VCPUS => MAX_VCPUS as usize,
+            KVM_CAP_NR_MEMSLOTS => MAX_MEMORY_SLOTS as usize,
+            _ => 0,
+        }),
+        KVM_CREATE_VM => {
+            // Only the default machine type
+            if arg != 0 {
+                return Err(EINVAL);
+            }
+            let mut handles = HANDLES.lock();
+            let id = handles.next_vm_id;
+            handles.next_vm_id += 1;
+            let vm = Vm::new(id).map_err(errno)?;
+            crate::serial_println!("[VIRT] Process {} created VM {}", pid, id);
+            handles.insert(pid, Handle::Vm(Arc::new(vm)))
+        }
+        _ => Err(ENOTTY),
+    }
+}
+
+fn vm_ioctl(pid: u32, vm: &Arc<Vm>, request: usize, arg: usize) -> Result<usize, usize> {
+    match request {
+        KVM_CREATE_VCPU => {
+            if arg >= MAX_VCPUS as usize {
+                return Err(EINVAL);
+            }
+            let vcpu = vm.create_vcpu(arg as u32).map_err(errno)?;
+            HANDLES.lock().insert(pid, Handle::Vcpu(vm.clone(), Arc::new(Mutex::new(vcpu))))
+        }
+        KVM_SET_USER_MEMORY_REGION => {
+            let region: UserMemoryRegion = read_user(arg)?;
+            if region.slot >= MAX_MEMORY_SLOTS || region.flags & !KVM_MEM_READONLY != 0 {
+                return Err(EINVAL);
+            }
+            vm.set_memory_slot(MemorySlot {
+                slot: region.slot,
+                guest_phys: region.guest_phys_addr,
+                size: region.memory_size,
+                user_addr: region.userspace_addr,
+                read_only: region.flags & KVM_MEM_READONLY != 0,
+            })
+            .map_err(errno)?;
+            Ok(0)
+        }
+        _ => Err(ENOTTY),
+    }
+}
+
+fn vcpu_ioctl(vm: &Vm, vcpu: &Mutex<Vcpu>, request: usize, arg: usize) -> Result<usize, usize> {
+    // Another thread may be running it
+    let mut vcpu = vcpu.try_lock().ok_or(EBUSY)?;
+
+    match request {
+        KVM_RUN => {
+            let mut run: RunState = read_user(arg)?;
+            let exit = vcpu.run(vm, run.io_data).map_err(errno)?;
+            fill_run_state(&mut run, exit, &vcpu);
+            write_user(arg, &run)?;
+            Ok(0)
+        }
+        KVM_GET_REGS => write_user(arg, &vcpu.regs()).map(|()| 0),
+        KVM_SET_REGS => {
+            let regs: Regs = read_user(arg)?;
+            vcpu.set_regs(regs);
+            Ok(0)
+        }
+        KVM_GET_SREGS => write_user(arg, &vcpu.sregs()).map(|()| 0),
+        KVM_SET_SREGS => {
+            let sregs: Sregs = read_user(arg)?;
+            vcpu.set_sregs(sregs);
+            Ok(0)
+        }
+        KVM_INTERRUPT => {
+            let irq: u32 = read_user(arg)?;
+            if irq > 0xff {
+                return Err(EINVAL);
+            }
+            vcpu.interrupt(irq as u8).map_err(errno)?;
+            Ok(0)
+        }
+        _ => Err(ENOTTY),
+    }
+}
+
+fn fill_run_state(run: &mut RunState, exit: Exit, vcpu: &Vcpu) {
+    *run = RunState {
+        ready_for_interrupt_injection: vcpu.ready_for_interrupt() as u8,
+        if_flag: (vcpu.regs().rflags & super::RFLAGS_IF != 0) as u8,
+        ..RunState::default()
+    };
+
+    match exit {
+        Exit::Io { port, size, write, data } => {
+            run.exit_reason = KVM_EXIT_IO;
+            run.io_direction = if write { KVM_EXIT_IO_OUT } else { KVM_EXIT_IO_IN };
+            run.io_size = size;
+            run.io_port = port;
+            run.io_data = if write { data } else { 0 };
+        }
+        Exit::Mmio { address, write } => {
+            run.exit_reason = KVM_EXIT_MMIO;
+            run.mmio_phys_addr = address;
+            run.mmio_is_write = write as u8;
+        }
+        Exit::Hlt => run.exit_reason = KVM_EXIT_HLT,
+        Exit::Shutdown => run.exit_reason = KVM_EXIT_SHUTDOWN,
+        Exit::FailEntry(reason) => {
+            run.exit_reason = KVM_EXIT_FAIL_ENTRY;
+            run.hardware_exit_reason = reason;
+        }
+        Exit::Unknown(reason) => {
+            run.exit_reason = KVM_EXIT_UNKNOWN;
+            run.hardware_exit_reason = reason;
+        }
+        Exit::EmulationFailure => run.exit_reason = KVM_EXIT_INTERNAL_ERROR,
+    }
+}
+
+/// An open VM, for the shell
+pub struct VmInfo {
+    pub id: u32,
+    pub owner: u32,
+    pub slots: usize,
+    pub vcpus: usize,
+}
+
+pub fn vms() -> Vec<VmInfo> {
+    let handles = HANDLES.lock();
+    let mut vms: Vec<VmInfo> = Vec::new();
+    for (&(pid, _), handle) in handles.open.iter() {
+        if let Handle::Vm(vm) = handle {
+            if !vms.iter().any(|info| info.id == vm.id) {
+                vms.push(VmInfo {
+                    id: vm.id,
+                    owner: pid,
+                    slots: vm.slots().len(),
+                    vcpus: vm.vcpu_count(),
+                });
+            }
+        }
+    }
+    vms
+}
@@ -0,0 +1,212 @@
+//! Guest-physical memory: slots of VMM memory and the EPT or NPT tables
+//! that map them. Tables start empty and pages are mapped on the first
+//! fault, so a slot can be registered before the VMM has touched its pages.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{Backend, VirtError};
+use crate::memory::userspace::validate_user_buffer;
+
+const PAGE_SIZE: u64 = 4096;
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// EPT entry bits
+const EPT_READ: u64 = 1 << 0;
+const EPT_WRITE: u64 = 1 << 1;
+const EPT_EXECUTE: u64 = 1 << 2;
+const EPT_MEMORY_TYPE_WB: u64 = 6 << 3;
+
+// NPT entries are ordinary page table entries
+const NPT_PRESENT: u64 = 1 << 0;
+const NPT_WRITABLE: u64 = 1 << 1;
+const NPT_USER: u64 = 1 << 2;
+
+/// A range of guest-physical memory backed by VMM memory, as given to
+/// KVM_SET_USER_MEMORY_REGION
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySlot {
+    pub slot: u32,
+    pub guest_phys: u64,
+    pub size: u64,
+    pub user_addr: u64,
+    pub read_only: bool,
+}
+
+impl MemorySlot {
+    fn contains(&self, guest_phys: u64) -> bool {
+        guest_phys >= self.guest_phys && guest_phys - self.guest_phys < self.size
+    }
+
+    fn overlaps(&self, other: &MemorySlot) -> bool {
+        self.guest_phys < other.guest_phys + other.size && other.guest_phys < self.guest_phys + self.size
+    }
+}
+
+/// What a fault on guest-physical memory turned out to be
+pub enum Fault {
+    /// Now mapped; re-enter the guest
+    Mapped,
+    /// Not RAM, or a write to read-only RAM: the VMM emulates it
+    Mmio,
+}
+
+pub struct GuestMemory {
+    slots: Vec<MemorySlot>,
+    tables: GuestPageTables,
+    /// Bumped whenever mappings are removed; vCPUs flush their TLBs when
+    /// theirs is older
+    pub generation: u64,
+}
+
+impl GuestMemory {
+    pub fn new(backend: Backend) -> Self {
+        GuestMemory {
+            slots: Vec::new(),
+            tables: GuestPageTables::new(backend),
+            generation: 0,
+        }
+    }
+
+    pub fn slots(&self) -> &[MemorySlot] {
+        &self.slots
+    }
+
+    /// Add, move or resize a slot; a size of 0 deletes it
+    pub fn set_slot(&mut self, slot: MemorySlot) -> Result<(), VirtError> {
+        if slot.guest_phys % PAGE_SIZE != 0 || slot.size % PAGE_SIZE != 0 || slot.user_addr % PAGE_SIZE != 0 {
+            return Err(VirtError::InvalidArgument);
+        }
+        let user_addr = VirtAddr::try_new(slot.user_addr).map_err(|_| VirtError::BadAddress)?;
+        if slot.size != 0 && !validate_user_buffer(user_addr, slot.size as usize) {
+            return Err(VirtError::BadAddress);
+        }
+        if self.slots.iter().any(|other| other.slot != slot.slot && other.overlaps(&slot)) {
+            return Err(VirtError::InvalidArgument);
+        }
+
+        if let Some(index) = self.slots.iter().position(|old| old.slot == slot.slot) {
+            let old = self.slots.remove(index);
+            self.tables.unmap(old.guest_phys, old.size);
+            self.generation += 1;
+        }
+        if slot.size != 0 {
+            self.slots.push(slot);
+        }
+        Ok(())
+    }
+
+    /// Map the page behind a faulting guest-physical address. Runs in the
+    /// VMM's syscall, so its page tables are the active ones.
+    pub fn handle_fault(&mut self, guest_phys: u64, write: bool) -> Result<Fault, VirtError> {
+        let slot = match self.slots.iter().find(|slot| slot.contains(guest_phys)) {
+            Some(slot) if !(write && slot.read_only) => *slot,
+            _ => return Ok(Fault::Mmio),
+        };
+
+        let page = guest_phys & !(PAGE_SIZE - 1);
+        let user_addr = slot.user_addr + (page - slot.guest_phys);
+        let (host_phys, flags) = crate::memory::paging::translate_current(VirtAddr::new(user_addr))
+            .ok_or(VirtError::BadAddress)?;
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err(VirtError::BadAddress);
+        }
+        // Never let the guest write what the VMM itself cannot
+        let writable = !slot.read_only && flags.contains(PageTableFlags::WRITABLE);
+        if write && !writable {
+            return Err(VirtError::BadAddress);
+        }
+
+        self.tables.map(page, host_phys.as_u64() & ADDRESS_MASK, writable)?;
+        Ok(Fault::Mapped)
+    }
+
+    /// EPTP or nCR3 for the VMCS or VMCB
+    pub fn root(&self) -> u64 {
+        self.tables.root()
+    }
+}
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+/// Four-level EPT or NPT tables mapping 4 KiB pages
+struct GuestPageTables {
+    backend: Backend,
+    root: Box<Table>,
+    // Lower levels, kept alive until the VM goes away
+    tables: Vec<Box<Table>>,
+}
+
+impl GuestPageTables {
+    fn new(backend: Backend) -> Self {
+        GuestPageTables {
+            backend,
+            root: Box::new(Table([0; 512])),
+            tables: Vec::new(),
+        }
+    }
+
+    fn root(&self) -> u64 {
+        let root = &*self.root as *const Table as u64;
+        match self.backend {
+            // Write-back, four-level walk
+            Backend::Vmx => root | (3 << 3) | 6,
+            Backend::Svm => root,
+        }
+    }
+
+    fn table_entry(&self) -> u64 {
+        match self.backend {
+            Backend::Vmx => EPT_READ | EPT_WRITE | EPT_EXECUTE,
+            Backend::Svm => NPT_PRESENT | NPT_WRITABLE | NPT_USER,
+        }
+    }
+
+    fn leaf_entry(&self, host_phys: u64, writable: bool) -> u64 {
+        match self.backend {
+            Backend::Vmx => host_phys | EPT_READ | EPT_EXECUTE | EPT_MEMORY_TYPE_WB | if writable { EPT_WRITE } else { 0 },
+            Backend::Svm => host_phys | NPT_PRESENT | NPT_USER | if writable { NPT_WRITABLE } else { 0 },
+        }
+    }
+
+    /// Leaf entry for `guest_phys`, creating the tables above it if asked
+    fn leaf(&mut self, guest_phys: u64, create: bool) -> Option<*mut u64> {
+        let table_entry = self.table_entry();
+        let mut table: *mut Table = &mut *self.root;
+        for level in (2..=4u64).rev() {
+            let index = ((guest_phys >> (12 + 9 * (level - 1))) & 0x1ff) as usize;
+            let entry = unsafe { &mut (*table).0[index] };
+            if *entry == 0 {
+                if !create {
+                    return None;
+                }
+                let mut next = Box::new(Table([0; 512]));
+                *entry = (&mut *next as *mut Table as u64) | table_entry;
+                self.tables.push(next);
+            }
+            table = (*entry & ADDRESS_MASK) as *mut Table;
+        }
+        let index = ((guest_phys >> 12) & 0x1ff) as usize;
+        Some(unsafe { &mut (*table).0[index] as *mut u64 })
+    }
+
+    fn map(&mut self, guest_phys: u64, host_phys: u64, writable: bool) -> Result<(), VirtError> {
+        let entry = self.leaf_entry(host_phys, writable);
+        let leaf = self.leaf(guest_phys, true).ok_or(VirtError::OutOfMemory)?;
+        unsafe { leaf.write_volatile(entry) };
+        Ok(())
+    }
+
+    fn unmap(&mut self, guest_phys: u64, size: u64) {
+        let mut page = guest_phys;
+        while page < guest_phys + size {
+            if let Some(leaf) = self.leaf(page, false) {
+                unsafe { leaf.write_volatile(0) };
+            }
+            page += PAGE_SIZE;
+        }
+    }
+}
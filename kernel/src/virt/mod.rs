@@ -0,0 +1,597 @@
+//! Hardware virtualization host (Intel VT-x and AMD-V)
+//!
+//! Userspace VMMs drive guests through the /dev/kvm-style handles in
+//! `ioctl`: open the device, create a VM, back its guest-physical memory
+//! with slots of the VMM's own pages, create vCPUs and run them. Each vCPU
+//! owns a VMCS (`vmx`) or VMCB (`svm`), and guest-physical memory is mapped
+//! through EPT or NPT tables filled in on demand from the slots (`memory`).
+//! CPUID, MSRs and control register writes are handled here; port I/O,
+//! accesses outside the slots and HLT go back to the VMM as exits.
+//!
+//! The older `hypervisor` module models VMs for the virtualization tests;
+//! this is the interface userspace reaches through syscalls.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::sync::Mutex;
+
+pub mod ioctl;
+mod memory;
+mod svm;
+mod vmx;
+
+pub use memory::MemorySlot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Vmx,
+    Svm,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Vmx => "Intel VT-x",
+            Backend::Svm => "AMD-V",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtError {
+    NotSupported,
+    DisabledByFirmware,
+    EnableFailed,
+    OutOfMemory,
+    InvalidArgument,
+    BadAddress,
+    NotFound,
+    Busy,
+}
+
+impl fmt::Display for VirtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NotSupported => write!(f, "Hardware virtualization not supported"),
+            Self::DisabledByFirmware => write!(f, "Virtualization disabled by firmware"),
+            Self::EnableFailed => write!(f, "Could not enter virtualization mode"),
+            Self::OutOfMemory => write!(f, "Out of memory"),
+            Self::InvalidArgument => write!(f, "Invalid argument"),
+            Self::BadAddress => write!(f, "Bad address"),
+            Self::NotFound => write!(f, "No such VM or vCPU"),
+            Self::Busy => write!(f, "vCPU is bound to another CPU"),
+        }
+    }
+}
+
+// 0 until init() finds a usable backend
+static BACKEND: AtomicU8 = AtomicU8::new(0);
+
+// VPIDs and ASIDs; 0 belongs to the host
+static NEXT_TAG: AtomicU32 = AtomicU32::new(1);
+
+pub fn init() {
+    let backend = if vmx::supported() {
+        Some(Backend::Vmx)
+    } else if svm::supported() {
+        Some(Backend::Svm)
+    } else {
+        None
+    };
+
+    match backend {
+        Some(backend) => {
+            BACKEND.store(backend as u8 + 1, Ordering::SeqCst);
+            crate::serial_println!("[VIRT] {} available, /dev/kvm enabled", backend.name());
+        }
+        None => crate::serial_println!("[VIRT] No usable hardware virtualization (needs VT-x with EPT and unrestricted guests, or AMD-V with NPT)"),
+    }
+}
+
+pub fn backend() -> Option<Backend> {
+    match BACKEND.load(Ordering::Relaxed) {
+        1 => Some(Backend::Vmx),
+        2 => Some(Backend::Svm),
+        _ => None,
+    }
+}
+
+fn current_cpu() -> usize {
+    crate::smp::current_cpu_id() as usize % crate::smp::MAX_CPUS
+}
+
+/// 4 KiB page-aligned, zeroed block for hardware structures. Kernel heap
+/// memory is identity mapped, so its address is also its physical address.
+#[repr(C, align(4096))]
+pub(crate) struct Page(pub [u8; 4096]);
+
+impl Page {
+    fn new() -> Box<Self> {
+        Box::new(Page([0; 4096]))
+    }
+
+    fn phys(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+/// General-purpose registers, laid out like `struct kvm_regs`. The entry
+/// stubs load and save every field but rsp, rip and rflags, which live in
+/// the VMCS or VMCB.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Regs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Regs {
+    /// Register by its instruction encoding (rax, rcx, rdx, rbx, rsp, ...)
+    fn gpr(&self, index: u64) -> u64 {
+        let mut regs = *self;
+        *regs.gpr_mut(index)
+    }
+
+    fn set_gpr(&mut self, index: u64, value: u64) {
+        *self.gpr_mut(index) = value;
+    }
+
+    fn gpr_mut(&mut self, index: u64) -> &mut u64 {
+        match index & 0xf {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        }
+    }
+
+    fn set_edx_eax(&mut self, value: u64) {
+        self.rax = value & 0xffff_ffff;
+        self.rdx = value >> 32;
+    }
+
+    fn edx_eax(&self) -> u64 {
+        (self.rdx << 32) | (self.rax & 0xffff_ffff)
+    }
+}
+
+/// Segment register, laid out like `struct kvm_segment`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Segment {
+    pub base: u64,
+    pub limit: u32,
+    pub selector: u16,
+    pub type_: u8,
+    pub present: u8,
+    pub dpl: u8,
+    pub db: u8,
+    pub s: u8,
+    pub l: u8,
+    pub g: u8,
+    pub avl: u8,
+    pub unusable: u8,
+    pub padding: u8,
+}
+
+impl Segment {
+    const fn real_mode(selector: u16, base: u64, type_: u8, s: u8) -> Self {
+        Segment {
+            base,
+            limit: 0xffff,
+            selector,
+            type_,
+            present: 1,
+            dpl: 0,
+            db: 0,
+            s,
+            l: 0,
+            g: 0,
+            avl: 0,
+            unusable: 0,
+            padding: 0,
+        }
+    }
+}
+
+/// GDTR or IDTR, laid out like `struct kvm_dtable`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u16,
+    pub padding: [u16; 3],
+}
+
+/// System registers, laid out like `struct kvm_sregs`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sregs {
+    pub cs: Segment,
+    pub ds: Segment,
+    pub es: Segment,
+    pub fs: Segment,
+    pub gs: Segment,
+    pub ss: Segment,
+    pub tr: Segment,
+    pub ldt: Segment,
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub cr8: u64,
+    pub efer: u64,
+    pub apic_base: u64,
+    pub interrupt_bitmap: [u64; 4],
+}
+
+impl Sregs {
+    /// Architectural state after INIT: real mode at F000:FFF0
+    fn reset() -> Self {
+        let data = Segment::real_mode(0, 0, 0x3, 1);
+        Sregs {
+            cs: Segment::real_mode(0xf000, 0xffff_0000, 0xb, 1),
+            ds: data,
+            es: data,
+            fs: data,
+            gs: data,
+            ss: data,
+            tr: Segment::real_mode(0, 0, 0xb, 0),
+            ldt: Segment::real_mode(0, 0, 0x2, 0),
+            gdt: DescriptorTable { base: 0, limit: 0xffff, padding: [0; 3] },
+            idt: DescriptorTable { base: 0, limit: 0xffff, padding: [0; 3] },
+            cr0: CR0_ET | CR0_CD | CR0_NW,
+            apic_base: APIC_BASE_DEFAULT,
+            ..Default::default()
+        }
+    }
+}
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_ET: u64 = 1 << 4;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+// xAPIC at its usual address, enabled, on the bootstrap processor
+const APIC_BASE_DEFAULT: u64 = 0xfee0_0000 | (1 << 11) | (1 << 8);
+
+const MSR_TSC: u32 = 0x10;
+const MSR_APIC_BASE: u32 = 0x1b;
+const MSR_MISC_ENABLE: u32 = 0x1a0;
+
+// Exceptions the exit handlers inject
+const VECTOR_UD: u8 = 6;
+const VECTOR_GP: u8 = 13;
+
+/// Why a vCPU stopped running, as far as the VMM is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// IN or OUT; for IN the VMM supplies the data before the next run
+    Io { port: u16, size: u8, write: bool, data: u32 },
+    /// Access to guest-physical memory outside every slot, or a write to a
+    /// read-only slot. The VMM emulates the instruction at rip.
+    Mmio { address: u64, write: bool },
+    Hlt,
+    /// Triple fault
+    Shutdown,
+    /// VM entry failed; the hardware reason is backend specific
+    FailEntry(u64),
+    /// Exit the kernel does not handle, with the hardware exit reason
+    Unknown(u64),
+    /// String I/O, which is not emulated
+    EmulationFailure,
+}
+
+/// vCPU state shared by both backends
+pub(crate) struct VcpuState {
+    regs: Regs,
+    sregs: Sregs,
+    // Sregs changed by the VMM since the last entry
+    sregs_dirty: bool,
+    // MSRs with no hardware effect, kept for the guest to read back
+    msrs: BTreeMap<u32, u64>,
+    // Interrupt vector the VMM asked to inject
+    pending_interrupt: Option<u8>,
+    // Size of an IN whose data the VMM provides on the next run
+    pending_in: Option<u8>,
+    // CPU whose VMCS or VMCB caches hold this vCPU
+    cpu: Option<usize>,
+    // Memory generation whose translations the TLBs may hold
+    memory_generation: u64,
+    fpu: FxArea,
+}
+
+impl VcpuState {
+    fn new() -> Self {
+        VcpuState {
+            regs: Regs {
+                rip: 0xfff0,
+                rflags: 0x2,
+                ..Default::default()
+            },
+            sregs: Sregs::reset(),
+            sregs_dirty: true,
+            msrs: BTreeMap::new(),
+            pending_interrupt: None,
+            pending_in: None,
+            cpu: None,
+            memory_generation: 0,
+            fpu: FxArea::new(),
+        }
+    }
+
+    /// MSRs with no state in the VMCS or VMCB
+    fn read_msr(&self, msr: u32) -> u64 {
+        match msr {
+            MSR_TSC => unsafe { core::arch::x86_64::_rdtsc() },
+            MSR_APIC_BASE => self.sregs.apic_base,
+            // Fast strings
+            MSR_MISC_ENABLE => self.msrs.get(&msr).copied().unwrap_or(1),
+            // Anything else reads back what the guest wrote, or 0
+            _ => self.msrs.get(&msr).copied().unwrap_or(0),
+        }
+    }
+
+    fn write_msr(&mut self, msr: u32, value: u64) {
+        match msr {
+            MSR_TSC => {}
+            MSR_APIC_BASE => self.sregs.apic_base = value,
+            _ => {
+                self.msrs.insert(msr, value);
+            }
+        }
+    }
+
+    /// Whether an external interrupt can be delivered right now
+    fn can_inject(&self, blocked_by_shadow: bool) -> bool {
+        self.regs.rflags & RFLAGS_IF != 0 && !blocked_by_shadow
+    }
+}
+
+/// FXSAVE image. Guest and host x87/SSE state are swapped around every
+/// entry; XSAVE is hidden from guests, so this covers everything they use.
+#[repr(C, align(16))]
+struct FxArea([u8; 512]);
+
+impl FxArea {
+    fn new() -> Self {
+        let mut area = FxArea([0; 512]);
+        // FCW after FNINIT, and the default MXCSR
+        area.0[0..2].copy_from_slice(&0x037fu16.to_le_bytes());
+        area.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        area
+    }
+
+    fn save(&mut self) {
+        unsafe { core::arch::asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack)) };
+    }
+
+    fn restore(&self) {
+        unsafe { core::arch::asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack)) };
+    }
+}
+
+// Leaf 7 features whose state only XSAVE can switch
+const LEAF7_EBX_XSAVE_FEATURES: u32 = (1 << 5) | (1 << 16) | (1 << 17) | (1 << 21) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 30) | (1 << 31);
+const LEAF7_ECX_XSAVE_FEATURES: u32 = (1 << 1) | (1 << 3) | (1 << 6) | (1 << 11) | (1 << 12) | (1 << 14);
+const LEAF7_EDX_XSAVE_FEATURES: u32 = (1 << 2) | (1 << 3) | (1 << 8) | (1 << 22) | (1 << 23) | (1 << 24) | (1 << 25);
+
+/// CPUID as guests see it: the host's, without the virtualization
+/// extensions, XSAVE, MONITOR or PMU, and with the hypervisor bit set
+fn guest_cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+    const SIGNATURE: &[u8; 12] = b"RustOSVirt\0\0";
+
+    match leaf {
+        0x4000_0000 => [
+            0x4000_0000,
+            u32::from_le_bytes([SIGNATURE[0], SIGNATURE[1], SIGNATURE[2], SIGNATURE[3]]),
+            u32::from_le_bytes([SIGNATURE[4], SIGNATURE[5], SIGNATURE[6], SIGNATURE[7]]),
+            u32::from_le_bytes([SIGNATURE[8], SIGNATURE[9], SIGNATURE[10], SIGNATURE[11]]),
+        ],
+        0x4000_0001..=0x4fff_ffff | 0xa | 0xd => [0; 4],
+        _ => {
+            let result = core::arch::x86_64::__cpuid_count(leaf, subleaf);
+            let mut regs = [result.eax, result.ebx, result.ecx, result.edx];
+            match leaf {
+                1 => {
+                    // MONITOR, VMX, PDCM, XSAVE, OSXSAVE, AVX off; hypervisor on
+                    regs[2] &= !((1 << 3) | (1 << 5) | (1 << 15) | (1 << 26) | (1 << 27) | (1 << 28));
+                    regs[2] |= 1 << 31;
+                }
+                7 if subleaf == 0 => {
+                    // AVX2, AVX-512, PKU and AMX need XSAVE
+                    regs[1] &= !LEAF7_EBX_XSAVE_FEATURES;
+                    regs[2] &= !LEAF7_ECX_XSAVE_FEATURES;
+                    regs[3] &= !LEAF7_EDX_XSAVE_FEATURES;
+                }
+                0x8000_0001 => regs[2] &= !(1 << 2), // SVM
+                0x8000_000a => regs = [0; 4],
+                _ => {}
+            }
+            regs
+        }
+    }
+}
+
+/// A guest: its memory slots and the vCPUs created in it
+pub struct Vm {
+    pub id: u32,
+    memory: Mutex<memory::GuestMemory>,
+    vcpus: Mutex<Vec<u32>>,
+}
+
+impl Vm {
+    pub fn new(id: u32) -> Result<Self, VirtError> {
+        let backend = backend().ok_or(VirtError::NotSupported)?;
+        Ok(Vm {
+            id,
+            memory: Mutex::new(memory::GuestMemory::new(backend)),
+            vcpus: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn set_memory_slot(&self, slot: MemorySlot) -> Result<(), VirtError> {
+        self.memory.lock().set_slot(slot)
+    }
+
+    pub fn slots(&self) -> Vec<MemorySlot> {
+        self.memory.lock().slots().to_vec()
+    }
+
+    pub fn create_vcpu(&self, id: u32) -> Result<Vcpu, VirtError> {
+        let mut vcpus = self.vcpus.lock();
+        if vcpus.contains(&id) {
+            return Err(VirtError::InvalidArgument);
+        }
+
+        let tag = NEXT_TAG.fetch_add(1, Ordering::Relaxed);
+        let arch = match backend().ok_or(VirtError::NotSupported)? {
+            Backend::Vmx => VcpuArch::Vmx(vmx::VmxVcpu::new(tag as u16)?),
+            Backend::Svm => VcpuArch::Svm(svm::SvmVcpu::new(tag)?),
+        };
+        vcpus.push(id);
+
+        Ok(Vcpu {
+            id,
+            state: VcpuState::new(),
+            arch,
+        })
+    }
+
+    pub fn vcpu_count(&self) -> usize {
+        self.vcpus.lock().len()
+    }
+}
+
+enum VcpuArch {
+    Vmx(vmx::VmxVcpu),
+    Svm(svm::SvmVcpu),
+}
+
+pub struct Vcpu {
+    pub id: u32,
+    state: VcpuState,
+    arch: VcpuArch,
+}
+
+impl Vcpu {
+    pub fn regs(&self) -> Regs {
+        self.state.regs
+    }
+
+    pub fn set_regs(&mut self, regs: Regs) {
+        // Bit 1 of RFLAGS is reserved and always set
+        self.state.regs = Regs { rflags: regs.rflags | 0x2, ..regs };
+    }
+
+    pub fn sregs(&self) -> Sregs {
+        self.state.sregs
+    }
+
+    pub fn set_sregs(&mut self, sregs: Sregs) {
+        self.state.sregs = sregs;
+        self.state.sregs_dirty = true;
+    }
+
+    /// Queue an external interrupt, delivered once the guest can take it
+    pub fn interrupt(&mut self, vector: u8) -> Result<(), VirtError> {
+        if self.state.pending_interrupt.is_some() {
+            return Err(VirtError::Busy);
+        }
+        self.state.pending_interrupt = Some(vector);
+        Ok(())
+    }
+
+    /// Whether an interrupt queued now would be delivered on the next entry
+    pub fn ready_for_interrupt(&self) -> bool {
+        self.state.pending_interrupt.is_none() && self.state.regs.rflags & RFLAGS_IF != 0
+    }
+
+    /// Run until an exit the VMM must see. `in_data` completes a pending IN.
+    pub fn run(&mut self, vm: &Vm, in_data: u32) -> Result<Exit, VirtError> {
+        // The VMCS or VMCB may be cached on the CPU that last ran it, and
+        // there is no cross-CPU call to flush it, so a vCPU stays put
+        let cpu = current_cpu();
+        match self.state.cpu {
+            Some(bound) if bound != cpu => return Err(VirtError::Busy),
+            _ => self.state.cpu = Some(cpu),
+        }
+
+        if let Some(size) = self.state.pending_in.take() {
+            let mask = match size {
+                1 => 0xff,
+                2 => 0xffff,
+                _ => 0xffff_ffff,
+            };
+            self.state.regs.rax = (self.state.regs.rax & !mask) | (in_data as u64 & mask);
+        }
+
+        loop {
+            let exit = x86_64::instructions::interrupts::without_interrupts(|| {
+                let mut host_fpu = FxArea([0; 512]);
+                host_fpu.save();
+                self.state.fpu.restore();
+
+                let result = match &mut self.arch {
+                    VcpuArch::Vmx(vcpu) => vcpu.run(&mut self.state, vm),
+                    VcpuArch::Svm(vcpu) => vcpu.run(&mut self.state, vm),
+                };
+
+                self.state.fpu.save();
+                host_fpu.restore();
+                result
+            })?;
+
+            match exit {
+                Some(exit) => {
+                    if let Exit::Io { write: false, size, .. } = exit {
+                        self.state.pending_in = Some(size);
+                    }
+                    return Ok(exit);
+                }
+                // Handled in the kernel
+                None => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,627 @@
+//! AMD-V backend: SVM enabled on each CPU that runs a vCPU, a VMCB per
+//! vCPU, and the exits the kernel handles without going back to the VMM
+
+use alloc::boxed::Box;
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::memory::Fault;
+use super::{current_cpu, guest_cpuid, Exit, Page, Regs, Segment, Sregs, VcpuState, VirtError, Vm, VECTOR_GP, VECTOR_UD};
+use crate::cpu::{read_msr, write_msr};
+use crate::smp::MAX_CPUS;
+
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_VM_CR: u32 = 0xc001_0114;
+const MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+
+const MSR_SYSENTER_CS: u32 = 0x174;
+const MSR_SYSENTER_ESP: u32 = 0x175;
+const MSR_SYSENTER_EIP: u32 = 0x176;
+const MSR_PAT: u32 = 0x277;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_CSTAR: u32 = 0xc000_0083;
+const MSR_SFMASK: u32 = 0xc000_0084;
+const MSR_FS_BASE: u32 = 0xc000_0100;
+const MSR_GS_BASE: u32 = 0xc000_0101;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+const EFER_SVME: u64 = 1 << 12;
+const VM_CR_SVMDIS: u64 = 1 << 4;
+
+// VMCB control area
+const VMCB_INTERCEPT_MISC1: usize = 0x0c;
+const VMCB_INTERCEPT_MISC2: usize = 0x10;
+const VMCB_IOPM_BASE: usize = 0x40;
+const VMCB_MSRPM_BASE: usize = 0x48;
+const VMCB_ASID: usize = 0x58;
+const VMCB_TLB_CONTROL: usize = 0x5c;
+const VMCB_VINTR: usize = 0x60;
+const VMCB_INTERRUPT_SHADOW: usize = 0x68;
+const VMCB_EXIT_CODE: usize = 0x70;
+const VMCB_EXIT_INFO1: usize = 0x78;
+const VMCB_EXIT_INFO2: usize = 0x80;
+const VMCB_EXIT_INT_INFO: usize = 0x88;
+const VMCB_NP_ENABLE: usize = 0x90;
+const VMCB_EVENT_INJ: usize = 0xa8;
+const VMCB_N_CR3: usize = 0xb0;
+
+// VMCB state save area
+const VMCB_ES: usize = 0x400;
+const VMCB_CS: usize = 0x410;
+const VMCB_SS: usize = 0x420;
+const VMCB_DS: usize = 0x430;
+const VMCB_FS: usize = 0x440;
+const VMCB_GS: usize = 0x450;
+const VMCB_GDTR: usize = 0x460;
+const VMCB_LDTR: usize = 0x470;
+const VMCB_IDTR: usize = 0x480;
+const VMCB_TR: usize = 0x490;
+const VMCB_CPL: usize = 0x4cb;
+const VMCB_EFER: usize = 0x4d0;
+const VMCB_CR4: usize = 0x548;
+const VMCB_CR3: usize = 0x550;
+const VMCB_CR0: usize = 0x558;
+const VMCB_DR7: usize = 0x560;
+const VMCB_DR6: usize = 0x568;
+const VMCB_RFLAGS: usize = 0x570;
+const VMCB_RIP: usize = 0x578;
+const VMCB_RSP: usize = 0x5d8;
+const VMCB_RAX: usize = 0x5f8;
+const VMCB_STAR: usize = 0x600;
+const VMCB_LSTAR: usize = 0x608;
+const VMCB_CSTAR: usize = 0x610;
+const VMCB_SFMASK: usize = 0x618;
+const VMCB_KERNEL_GS_BASE: usize = 0x620;
+const VMCB_SYSENTER_CS: usize = 0x628;
+const VMCB_SYSENTER_ESP: usize = 0x630;
+const VMCB_SYSENTER_EIP: usize = 0x638;
+const VMCB_CR2: usize = 0x640;
+const VMCB_G_PAT: usize = 0x668;
+
+// First intercept vector
+const INTERCEPT_INTR: u32 = 1 << 0;
+const INTERCEPT_NMI: u32 = 1 << 1;
+const INTERCEPT_VINTR: u32 = 1 << 4;
+const INTERCEPT_RDPMC: u32 = 1 << 15;
+const INTERCEPT_CPUID: u32 = 1 << 18;
+const INTERCEPT_INVD: u32 = 1 << 22;
+const INTERCEPT_HLT: u32 = 1 << 24;
+const INTERCEPT_IOIO: u32 = 1 << 27;
+const INTERCEPT_MSR: u32 = 1 << 28;
+const INTERCEPT_SHUTDOWN: u32 = 1 << 31;
+
+// Second intercept vector: VMRUN (required), VMMCALL, VMLOAD, VMSAVE,
+// STGI, CLGI, SKINIT, then MONITOR, MWAIT, MWAIT_CONDITIONAL and XSETBV
+const INTERCEPT_MISC2: u32 = 0x7f | (0xf << 10);
+
+// V_IRQ, with the guest's TPR ignored for it
+const VINTR_IRQ: u64 = 1 << 8;
+const VINTR_IGNORE_TPR: u64 = 1 << 20;
+// Physical interrupts are masked by the host's IF, not the guest's
+const VINTR_MASKING: u64 = 1 << 24;
+
+const TLB_CONTROL_FLUSH_ALL: u32 = 1;
+
+const EXIT_INTR: u64 = 0x60;
+const EXIT_NMI: u64 = 0x61;
+const EXIT_VINTR: u64 = 0x64;
+const EXIT_RDPMC: u64 = 0x6f;
+const EXIT_CPUID: u64 = 0x72;
+const EXIT_INVD: u64 = 0x76;
+const EXIT_HLT: u64 = 0x78;
+const EXIT_IOIO: u64 = 0x7b;
+const EXIT_MSR: u64 = 0x7c;
+const EXIT_SHUTDOWN: u64 = 0x7f;
+const EXIT_VMRUN: u64 = 0x80;
+const EXIT_XSETBV: u64 = 0x8d;
+const EXIT_NPF: u64 = 0x400;
+const EXIT_INVALID: u64 = u64::MAX;
+
+// EVENTINJ and EXITINTINFO
+const EVENT_VALID: u64 = 1 << 31;
+const EVENT_ERROR_CODE_VALID: u64 = 1 << 11;
+const EVENT_TYPE_EXTERNAL: u64 = 0 << 8;
+const EVENT_TYPE_EXCEPTION: u64 = 3 << 8;
+
+// Per CPU: the host save area VMRUN uses, and the page VMSAVE stores the
+// host's FS, GS, TR, LDTR and syscall MSRs in; 0 while SVM is off there
+static HOST_SAVE_AREAS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static HOST_VMSAVE_PAGES: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether this CPU has AMD-V with nested paging, enabled by firmware
+pub fn supported() -> bool {
+    if core::arch::x86_64::__cpuid(0x8000_0000).eax < 0x8000_000a {
+        return false;
+    }
+    if core::arch::x86_64::__cpuid(0x8000_0001).ecx & (1 << 2) == 0 {
+        return false;
+    }
+    if read_msr(MSR_VM_CR) & VM_CR_SVMDIS != 0 {
+        crate::serial_println!("[VIRT] AMD-V disabled by firmware");
+        return false;
+    }
+    core::arch::x86_64::__cpuid(0x8000_000a).edx & 1 != 0
+}
+
+/// Number of ASIDs, including the host's
+fn asid_count() -> u32 {
+    core::arch::x86_64::__cpuid(0x8000_000a).ebx
+}
+
+/// Turn on SVM on this CPU, once
+fn enable_cpu(cpu: usize) -> Result<(), VirtError> {
+    if HOST_SAVE_AREAS[cpu].load(Ordering::Relaxed) != 0 {
+        return Ok(());
+    }
+    if read_msr(MSR_VM_CR) & VM_CR_SVMDIS != 0 {
+        return Err(VirtError::DisabledByFirmware);
+    }
+
+    write_msr(MSR_EFER, read_msr(MSR_EFER) | EFER_SVME);
+    // Both stay in use for as long as SVM is on
+    let save_area = Box::into_raw(Page::new()) as u64;
+    write_msr(MSR_VM_HSAVE_PA, save_area);
+    HOST_VMSAVE_PAGES[cpu].store(Box::into_raw(Page::new()) as u64, Ordering::Relaxed);
+    HOST_SAVE_AREAS[cpu].store(save_area, Ordering::Relaxed);
+    Ok(())
+}
+
+/// I/O or MSR permission map with every access intercepted
+#[repr(C, align(4096))]
+struct PermissionMap<const N: usize>([u8; N]);
+
+impl<const N: usize> PermissionMap<N> {
+    fn all_intercepted() -> Box<Self> {
+        // Built in place; the I/O map is too large for the kernel stack
+        let mut map = Box::<Self>::new_uninit();
+        unsafe {
+            map.as_mut_ptr().write_bytes(0xff, 1);
+            map.assume_init()
+        }
+    }
+
+    fn phys(&self) -> u64 {
+        self as *const Self as u64
+    }
+}
+
+pub struct SvmVcpu {
+    vmcb: Box<Page>,
+    io_map: Box<PermissionMap<{ 3 * 4096 }>>,
+    msr_map: Box<PermissionMap<{ 2 * 4096 }>>,
+    initialized: bool,
+    // Exception to inject, or an event an exit interrupted, as EVENTINJ
+    event: Option<u64>,
+}
+
+impl SvmVcpu {
+    pub fn new(asid: u32) -> Result<Self, VirtError> {
+        if asid >= asid_count() {
+            return Err(VirtError::NotSupported);
+        }
+
+        let mut vcpu = SvmVcpu {
+            vmcb: Page::new(),
+            io_map: PermissionMap::all_intercepted(),
+            msr_map: PermissionMap::all_intercepted(),
+            initialized: false,
+            event: None,
+        };
+        vcpu.write32(VMCB_ASID, asid);
+        Ok(vcpu)
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.vmcb.0[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write64(&mut self, offset: usize, value: u64) {
+        self.vmcb.0[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.vmcb.0[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write32(&mut self, offset: usize, value: u32) {
+        self.vmcb.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write16(&mut self, offset: usize, value: u16) {
+        self.vmcb.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes(self.vmcb.0[offset..offset + 2].try_into().unwrap())
+    }
+
+    /// Controls and state that stay the same for the life of the VMCB
+    fn setup(&mut self) {
+        self.write32(
+            VMCB_INTERCEPT_MISC1,
+            INTERCEPT_INTR
+                | INTERCEPT_NMI
+                | INTERCEPT_RDPMC
+                | INTERCEPT_CPUID
+                | INTERCEPT_INVD
+                | INTERCEPT_HLT
+                | INTERCEPT_IOIO
+                | INTERCEPT_MSR
+                | INTERCEPT_SHUTDOWN,
+        );
+        self.write32(VMCB_INTERCEPT_MISC2, INTERCEPT_MISC2);
+        let io_map = self.io_map.phys();
+        let msr_map = self.msr_map.phys();
+        self.write64(VMCB_IOPM_BASE, io_map);
+        self.write64(VMCB_MSRPM_BASE, msr_map);
+        self.write64(VMCB_VINTR, VINTR_MASKING);
+        self.write64(VMCB_NP_ENABLE, 1);
+        self.write64(VMCB_DR6, 0xffff_0ff0);
+        self.write64(VMCB_DR7, 0x400);
+        self.write64(VMCB_G_PAT, 0x0007_0406_0007_0406);
+    }
+
+    pub fn run(&mut self, state: &mut VcpuState, vm: &Vm) -> Result<Option<Exit>, VirtError> {
+        let cpu = current_cpu();
+        enable_cpu(cpu)?;
+        if !self.initialized {
+            self.setup();
+            self.initialized = true;
+        }
+
+        {
+            let memory = vm.memory.lock();
+            self.write64(VMCB_N_CR3, memory.root());
+            if state.memory_generation != memory.generation {
+                self.write32(VMCB_TLB_CONTROL, TLB_CONTROL_FLUSH_ALL);
+                state.memory_generation = memory.generation;
+            }
+        }
+
+        if state.sregs_dirty {
+            self.write_sregs(&state.sregs);
+            state.sregs_dirty = false;
+        }
+        self.write64(VMCB_RAX, state.regs.rax);
+        self.write64(VMCB_RSP, state.regs.rsp);
+        self.write64(VMCB_RIP, state.regs.rip);
+        self.write64(VMCB_RFLAGS, state.regs.rflags);
+        self.write64(VMCB_CR2, state.sregs.cr2);
+        self.inject_events(state);
+
+        let vmcb = self.vmcb.phys();
+        let host = HOST_VMSAVE_PAGES[cpu].load(Ordering::Relaxed);
+        unsafe {
+            // GIF stays clear around the switch; IF is set so that, with
+            // V_INTR_MASKING, host interrupts make the guest exit
+            asm!("clgi", "sti", "vmsave rax", in("rax") host);
+            svm_enter(&mut state.regs, vmcb);
+            asm!("cli", "vmload rax", "stgi", in("rax") host);
+        }
+
+        self.write32(VMCB_TLB_CONTROL, 0);
+        state.regs.rax = self.read64(VMCB_RAX);
+        state.regs.rsp = self.read64(VMCB_RSP);
+        state.regs.rip = self.read64(VMCB_RIP);
+        state.regs.rflags = self.read64(VMCB_RFLAGS);
+        state.sregs.cr2 = self.read64(VMCB_CR2);
+        self.read_sregs(&mut state.sregs);
+
+        // An event whose delivery the exit cut short is delivered again
+        let interrupted = self.read64(VMCB_EXIT_INT_INFO);
+        if interrupted & EVENT_VALID != 0 {
+            self.event = Some(interrupted);
+        }
+
+        self.handle_exit(state, vm)
+    }
+
+    fn inject_events(&mut self, state: &mut VcpuState) {
+        let mut intercepts = self.read32(VMCB_INTERCEPT_MISC1) & !INTERCEPT_VINTR;
+        let mut vintr = self.read64(VMCB_VINTR) & !(VINTR_IRQ | VINTR_IGNORE_TPR);
+        let mut inject = 0;
+
+        if let Some(event) = self.event.take() {
+            inject = event;
+        } else if let Some(vector) = state.pending_interrupt {
+            let shadow = self.read64(VMCB_INTERRUPT_SHADOW) & 1 != 0;
+            if state.can_inject(shadow) {
+                inject = EVENT_VALID | EVENT_TYPE_EXTERNAL | vector as u64;
+                state.pending_interrupt = None;
+            }
+        }
+        if state.pending_interrupt.is_some() {
+            // A virtual interrupt makes the guest exit once it can take one
+            intercepts |= INTERCEPT_VINTR;
+            vintr |= VINTR_IRQ | VINTR_IGNORE_TPR;
+        }
+
+        self.write64(VMCB_EVENT_INJ, inject);
+        self.write32(VMCB_INTERCEPT_MISC1, intercepts);
+        self.write64(VMCB_VINTR, vintr);
+    }
+
+    fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        let mut event = EVENT_VALID | EVENT_TYPE_EXCEPTION | vector as u64;
+        if let Some(code) = error_code {
+            event |= EVENT_ERROR_CODE_VALID | (code as u64) << 32;
+        }
+        self.event = Some(event);
+    }
+
+    /// Step over an intercepted instruction of known length
+    fn skip_instruction(&mut self, state: &mut VcpuState, len: u64) {
+        state.regs.rip += len;
+        self.write64(VMCB_INTERRUPT_SHADOW, 0);
+    }
+
+    fn handle_exit(&mut self, state: &mut VcpuState, vm: &Vm) -> Result<Option<Exit>, VirtError> {
+        let code = self.read64(VMCB_EXIT_CODE);
+        let info1 = self.read64(VMCB_EXIT_INFO1);
+        let info2 = self.read64(VMCB_EXIT_INFO2);
+
+        match code {
+            // Taken by the host once GIF and IF were set again
+            EXIT_INTR | EXIT_NMI => {
+                unsafe { asm!("sti", "nop", "cli") };
+                Ok(None)
+            }
+            EXIT_VINTR => Ok(None),
+            EXIT_SHUTDOWN => Ok(Some(Exit::Shutdown)),
+            EXIT_CPUID => {
+                let [eax, ebx, ecx, edx] = guest_cpuid(state.regs.rax as u32, state.regs.rcx as u32);
+                state.regs.rax = eax as u64;
+                state.regs.rbx = ebx as u64;
+                state.regs.rcx = ecx as u64;
+                state.regs.rdx = edx as u64;
+                self.skip_instruction(state, 2);
+                Ok(None)
+            }
+            EXIT_HLT => {
+                self.skip_instruction(state, 1);
+                Ok(Some(Exit::Hlt))
+            }
+            EXIT_INVD => {
+                self.skip_instruction(state, 2);
+                Ok(None)
+            }
+            EXIT_RDPMC => {
+                // The PMU is hidden from guests
+                self.inject_exception(VECTOR_GP, Some(0));
+                Ok(None)
+            }
+            EXIT_VMRUN..=EXIT_XSETBV => {
+                // Not offered to guests
+                self.inject_exception(VECTOR_UD, None);
+                Ok(None)
+            }
+            EXIT_IOIO => {
+                // String and REP I/O would need the guest's segments and
+                // page tables walked
+                if info1 & (1 << 2) != 0 {
+                    return Ok(Some(Exit::EmulationFailure));
+                }
+                let size = ((info1 >> 4) & 0x7) as u8;
+                let size = if size & 4 != 0 { 4 } else { size };
+                let write = info1 & 1 == 0;
+                let port = (info1 >> 16) as u16;
+                // EXITINFO2 holds the rip of the next instruction
+                state.regs.rip = info2;
+                self.write64(VMCB_INTERRUPT_SHADOW, 0);
+                Ok(Some(Exit::Io { port, size, write, data: state.regs.rax as u32 }))
+            }
+            EXIT_MSR => {
+                let msr = state.regs.rcx as u32;
+                if info1 == 0 {
+                    let value = self.read_guest_msr(state, msr);
+                    state.regs.set_edx_eax(value);
+                } else {
+                    let value = state.regs.edx_eax();
+                    self.write_guest_msr(state, msr, value);
+                }
+                self.skip_instruction(state, 2);
+                Ok(None)
+            }
+            EXIT_NPF => {
+                let write = info1 & (1 << 1) != 0;
+                match vm.memory.lock().handle_fault(info2, write)? {
+                    Fault::Mapped => Ok(None),
+                    Fault::Mmio => Ok(Some(Exit::Mmio { address: info2, write })),
+                }
+            }
+            EXIT_INVALID => Ok(Some(Exit::FailEntry(code))),
+            other => Ok(Some(Exit::Unknown(other))),
+        }
+    }
+
+    fn read_guest_msr(&self, state: &VcpuState, msr: u32) -> u64 {
+        match msr {
+            MSR_EFER => self.read64(VMCB_EFER) & !EFER_SVME,
+            MSR_FS_BASE => self.read64(VMCB_FS + 8),
+            MSR_GS_BASE => self.read64(VMCB_GS + 8),
+            MSR_KERNEL_GS_BASE => self.read64(VMCB_KERNEL_GS_BASE),
+            MSR_STAR => self.read64(VMCB_STAR),
+            MSR_LSTAR => self.read64(VMCB_LSTAR),
+            MSR_CSTAR => self.read64(VMCB_CSTAR),
+            MSR_SFMASK => self.read64(VMCB_SFMASK),
+            MSR_SYSENTER_CS => self.read64(VMCB_SYSENTER_CS),
+            MSR_SYSENTER_ESP => self.read64(VMCB_SYSENTER_ESP),
+            MSR_SYSENTER_EIP => self.read64(VMCB_SYSENTER_EIP),
+            MSR_PAT => self.read64(VMCB_G_PAT),
+            _ => state.read_msr(msr),
+        }
+    }
+
+    fn write_guest_msr(&mut self, state: &mut VcpuState, msr: u32, value: u64) {
+        let offset = match msr {
+            MSR_EFER => {
+                // VMRUN refuses guests without SVME; it is hidden from reads
+                self.write64(VMCB_EFER, value | EFER_SVME);
+                state.sregs.efer = value;
+                return;
+            }
+            MSR_FS_BASE => VMCB_FS + 8,
+            MSR_GS_BASE => VMCB_GS + 8,
+            MSR_KERNEL_GS_BASE => VMCB_KERNEL_GS_BASE,
+            MSR_STAR => VMCB_STAR,
+            MSR_LSTAR => VMCB_LSTAR,
+            MSR_CSTAR => VMCB_CSTAR,
+            MSR_SFMASK => VMCB_SFMASK,
+            MSR_SYSENTER_CS => VMCB_SYSENTER_CS,
+            MSR_SYSENTER_ESP => VMCB_SYSENTER_ESP,
+            MSR_SYSENTER_EIP => VMCB_SYSENTER_EIP,
+            MSR_PAT => VMCB_G_PAT,
+            _ => {
+                state.write_msr(msr, value);
+                return;
+            }
+        };
+        self.write64(offset, value);
+    }
+
+    fn write_segment(&mut self, offset: usize, segment: &Segment) {
+        self.write16(offset, segment.selector);
+        self.write16(offset + 2, attributes(segment));
+        self.write32(offset + 4, segment.limit);
+        self.write64(offset + 8, segment.base);
+    }
+
+    fn read_segment(&self, offset: usize) -> Segment {
+        let attributes = self.read16(offset + 2);
+        Segment {
+            base: self.read64(offset + 8),
+            limit: self.read32(offset + 4),
+            selector: self.read16(offset),
+            type_: (attributes & 0xf) as u8,
+            s: (attributes >> 4 & 1) as u8,
+            dpl: (attributes >> 5 & 3) as u8,
+            present: (attributes >> 7 & 1) as u8,
+            avl: (attributes >> 8 & 1) as u8,
+            l: (attributes >> 9 & 1) as u8,
+            db: (attributes >> 10 & 1) as u8,
+            g: (attributes >> 11 & 1) as u8,
+            unusable: (attributes >> 7 & 1 == 0) as u8,
+            padding: 0,
+        }
+    }
+
+    fn write_sregs(&mut self, sregs: &Sregs) {
+        self.write_segment(VMCB_ES, &sregs.es);
+        self.write_segment(VMCB_CS, &sregs.cs);
+        self.write_segment(VMCB_SS, &sregs.ss);
+        self.write_segment(VMCB_DS, &sregs.ds);
+        self.write_segment(VMCB_FS, &sregs.fs);
+        self.write_segment(VMCB_GS, &sregs.gs);
+        self.write_segment(VMCB_LDTR, &sregs.ldt);
+        self.write_segment(VMCB_TR, &sregs.tr);
+        self.write32(VMCB_GDTR + 4, sregs.gdt.limit as u32);
+        self.write64(VMCB_GDTR + 8, sregs.gdt.base);
+        self.write32(VMCB_IDTR + 4, sregs.idt.limit as u32);
+        self.write64(VMCB_IDTR + 8, sregs.idt.base);
+        self.vmcb.0[VMCB_CPL] = sregs.ss.dpl & 3;
+
+        self.write64(VMCB_CR0, sregs.cr0);
+        self.write64(VMCB_CR3, sregs.cr3);
+        self.write64(VMCB_CR4, sregs.cr4);
+        self.write64(VMCB_EFER, sregs.efer | EFER_SVME);
+        let vintr = self.read64(VMCB_VINTR) & !0xff;
+        self.write64(VMCB_VINTR, vintr | (sregs.cr8 & 0xf));
+    }
+
+    fn read_sregs(&self, sregs: &mut Sregs) {
+        sregs.es = self.read_segment(VMCB_ES);
+        sregs.cs = self.read_segment(VMCB_CS);
+        sregs.ss = self.read_segment(VMCB_SS);
+        sregs.ds = self.read_segment(VMCB_DS);
+        sregs.fs = self.read_segment(VMCB_FS);
+        sregs.gs = self.read_segment(VMCB_GS);
+        sregs.ldt = self.read_segment(VMCB_LDTR);
+        sregs.tr = self.read_segment(VMCB_TR);
+        sregs.gdt.limit = self.read32(VMCB_GDTR + 4) as u16;
+        sregs.gdt.base = self.read64(VMCB_GDTR + 8);
+        sregs.idt.limit = self.read32(VMCB_IDTR + 4) as u16;
+        sregs.idt.base = self.read64(VMCB_IDTR + 8);
+
+        sregs.cr0 = self.read64(VMCB_CR0);
+        sregs.cr3 = self.read64(VMCB_CR3);
+        sregs.cr4 = self.read64(VMCB_CR4);
+        sregs.efer = self.read64(VMCB_EFER) & !EFER_SVME;
+        sregs.cr8 = self.read64(VMCB_VINTR) & 0xf;
+    }
+}
+
+/// VMCB segment attributes: descriptor bits 40-47 and 52-55, packed
+fn attributes(segment: &Segment) -> u16 {
+    if segment.unusable != 0 {
+        return 0;
+    }
+    (segment.type_ as u16 & 0xf)
+        | (segment.s as u16 & 1) << 4
+        | (segment.dpl as u16 & 3) << 5
+        | (segment.present as u16 & 1) << 7
+        | (segment.avl as u16 & 1) << 8
+        | (segment.l as u16 & 1) << 9
+        | (segment.db as u16 & 1) << 10
+        | (segment.g as u16 & 1) << 11
+}
+
+/// Run the guest whose VMCB is at `vmcb` with the registers in `regs`,
+/// saving them back after the VM exit
+#[unsafe(naked)]
+unsafe extern "C" fn svm_enter(regs: *mut Regs, vmcb: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "push rdi",
+        // Guest rax lives in the VMCB; VMRUN takes the VMCB's address
+        "mov rax, rsi",
+        "vmload rax",
+        "mov rbx, [rdi + 8]",
+        "mov rcx, [rdi + 16]",
+        "mov rdx, [rdi + 24]",
+        "mov rsi, [rdi + 32]",
+        "mov rbp, [rdi + 56]",
+        "mov r8, [rdi + 64]",
+        "mov r9, [rdi + 72]",
+        "mov r10, [rdi + 80]",
+        "mov r11, [rdi + 88]",
+        "mov r12, [rdi + 96]",
+        "mov r13, [rdi + 104]",
+        "mov r14, [rdi + 112]",
+        "mov r15, [rdi + 120]",
+        "mov rdi, [rdi + 40]",
+        "vmrun rax",
+        // rax is the host's again: the VMCB address
+        "vmsave rax",
+        "push rdi",
+        "mov rdi, [rsp + 8]",
+        "mov [rdi + 8], rbx",
+        "mov [rdi + 16], rcx",
+        "mov [rdi + 24], rdx",
+        "mov [rdi + 32], rsi",
+        "mov [rdi + 56], rbp",
+        "mov [rdi + 64], r8",
+        "mov [rdi + 72], r9",
+        "mov [rdi + 80], r10",
+        "mov [rdi + 88], r11",
+        "mov [rdi + 96], r12",
+        "mov [rdi + 104], r13",
+        "mov [rdi + 112], r14",
+        "mov [rdi + 120], r15",
+        "pop rax",
+        "mov [rdi + 40], rax",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
@@ -0,0 +1,939 @@
+//! Intel VT-x backend: VMXON on each CPU that runs a vCPU, a VMCS per vCPU,
+//! and the exits the kernel handles without going back to the VMM
+
+use alloc::boxed::Box;
+use core::arch::{asm, naked_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr4};
+
+use super::memory::Fault;
+use super::{
+    current_cpu, guest_cpuid, Exit, Page, Regs, Segment, Sregs, VcpuState, VirtError, Vm, CR0_PE, CR0_PG, EFER_LMA,
+    EFER_LME, VECTOR_GP, VECTOR_UD,
+};
+use crate::cpu::{read_msr, write_msr};
+use crate::hypervisor::vmx::{vmread, vmwrite, VmcsField};
+use crate::smp::MAX_CPUS;
+
+const IA32_FEATURE_CONTROL: u32 = 0x3a;
+const IA32_VMX_BASIC: u32 = 0x480;
+const IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+const IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+const IA32_VMX_EXIT_CTLS: u32 = 0x483;
+const IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+const IA32_VMX_CR0_FIXED0: u32 = 0x486;
+const IA32_VMX_CR0_FIXED1: u32 = 0x487;
+const IA32_VMX_CR4_FIXED0: u32 = 0x488;
+const IA32_VMX_CR4_FIXED1: u32 = 0x489;
+const IA32_VMX_PROCBASED_CTLS2: u32 = 0x48b;
+const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+// The TRUE_ variants allow clearing default-1 controls
+const IA32_VMX_TRUE_OFFSET: u32 = 0xc;
+
+const FEATURE_CONTROL_LOCKED: u64 = 1 << 0;
+const FEATURE_CONTROL_VMXON: u64 = 1 << 2;
+
+const MSR_SYSENTER_CS: u32 = 0x174;
+const MSR_SYSENTER_ESP: u32 = 0x175;
+const MSR_SYSENTER_EIP: u32 = 0x176;
+const MSR_PAT: u32 = 0x277;
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_FS_BASE: u32 = 0xc000_0100;
+const MSR_GS_BASE: u32 = 0xc000_0101;
+
+// Switched through the VM-entry and VM-exit MSR lists
+const SWITCHED_MSRS: [u32; 5] = [
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SFMASK
+    0xc000_0102, // KERNEL_GS_BASE
+];
+
+const PIN_EXTERNAL_INTERRUPT_EXITING: u32 = 1 << 0;
+const PIN_NMI_EXITING: u32 = 1 << 3;
+
+const PROC_INTERRUPT_WINDOW_EXITING: u32 = 1 << 2;
+const PROC_HLT_EXITING: u32 = 1 << 7;
+const PROC_MWAIT_EXITING: u32 = 1 << 10;
+const PROC_RDPMC_EXITING: u32 = 1 << 11;
+const PROC_CR8_LOAD_EXITING: u32 = 1 << 19;
+const PROC_CR8_STORE_EXITING: u32 = 1 << 20;
+const PROC_UNCONDITIONAL_IO_EXITING: u32 = 1 << 24;
+const PROC_MONITOR_EXITING: u32 = 1 << 29;
+const PROC_SECONDARY_CONTROLS: u32 = 1 << 31;
+
+const PROC2_ENABLE_EPT: u32 = 1 << 1;
+const PROC2_ENABLE_RDTSCP: u32 = 1 << 3;
+const PROC2_ENABLE_VPID: u32 = 1 << 5;
+const PROC2_UNRESTRICTED_GUEST: u32 = 1 << 7;
+const PROC2_ENABLE_INVPCID: u32 = 1 << 12;
+
+const EXIT_HOST_ADDRESS_SPACE_SIZE: u32 = 1 << 9;
+const EXIT_SAVE_PAT: u32 = 1 << 18;
+const EXIT_LOAD_PAT: u32 = 1 << 19;
+const EXIT_SAVE_EFER: u32 = 1 << 20;
+const EXIT_LOAD_EFER: u32 = 1 << 21;
+
+const ENTRY_IA32E_MODE_GUEST: u32 = 1 << 9;
+const ENTRY_LOAD_PAT: u32 = 1 << 14;
+const ENTRY_LOAD_EFER: u32 = 1 << 15;
+
+// EPT capabilities the tables in memory.rs rely on
+const EPT_CAP_WALK_4: u64 = 1 << 6;
+const EPT_CAP_WRITE_BACK: u64 = 1 << 14;
+const EPT_CAP_INVEPT: u64 = 1 << 20;
+const EPT_CAP_INVEPT_SINGLE: u64 = 1 << 25;
+
+const EXIT_REASON_EXCEPTION_NMI: u32 = 0;
+const EXIT_REASON_EXTERNAL_INTERRUPT: u32 = 1;
+const EXIT_REASON_TRIPLE_FAULT: u32 = 2;
+const EXIT_REASON_INIT: u32 = 3;
+const EXIT_REASON_SIPI: u32 = 4;
+const EXIT_REASON_INTERRUPT_WINDOW: u32 = 7;
+const EXIT_REASON_CPUID: u32 = 10;
+const EXIT_REASON_HLT: u32 = 12;
+const EXIT_REASON_INVD: u32 = 13;
+const EXIT_REASON_RDPMC: u32 = 15;
+const EXIT_REASON_VMCALL: u32 = 18;
+const EXIT_REASON_VMXON: u32 = 27;
+const EXIT_REASON_CR_ACCESS: u32 = 28;
+const EXIT_REASON_IO_INSTRUCTION: u32 = 30;
+const EXIT_REASON_RDMSR: u32 = 31;
+const EXIT_REASON_WRMSR: u32 = 32;
+const EXIT_REASON_MWAIT: u32 = 36;
+const EXIT_REASON_MONITOR: u32 = 39;
+const EXIT_REASON_EPT_VIOLATION: u32 = 48;
+const EXIT_REASON_INVEPT: u32 = 50;
+const EXIT_REASON_INVVPID: u32 = 53;
+const EXIT_REASON_XSETBV: u32 = 55;
+const EXIT_REASON_ENTRY_FAILURE: u32 = 1 << 31;
+
+// VM-entry and exit interruption information
+const EVENT_VALID: u32 = 1 << 31;
+const EVENT_DELIVER_ERROR_CODE: u32 = 1 << 11;
+const EVENT_TYPE_EXTERNAL: u32 = 0 << 8;
+const EVENT_TYPE_NMI: u32 = 2 << 8;
+const EVENT_TYPE_HARDWARE_EXCEPTION: u32 = 3 << 8;
+const EVENT_TYPE_MASK: u32 = 7 << 8;
+
+// STI and MOV SS blocking in the guest interruptibility state
+const INTERRUPTIBILITY_SHADOW: u64 = 0x3;
+
+const CR4_VMXE: u64 = 1 << 13;
+
+// VMXON region per CPU, 0 while VMX is off there
+static VMXON_REGIONS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Whether this CPU has VT-x with everything the backend needs
+pub fn supported() -> bool {
+    if core::arch::x86_64::__cpuid(1).ecx & (1 << 5) == 0 {
+        return false;
+    }
+
+    let feature_control = read_msr(IA32_FEATURE_CONTROL);
+    if feature_control & FEATURE_CONTROL_LOCKED != 0 && feature_control & FEATURE_CONTROL_VMXON == 0 {
+        crate::serial_println!("[VIRT] VT-x disabled by firmware");
+        return false;
+    }
+
+    let procbased = read_msr(IA32_VMX_PROCBASED_CTLS) >> 32;
+    let secondary = read_msr(IA32_VMX_PROCBASED_CTLS2) >> 32;
+    let ept = read_msr(IA32_VMX_EPT_VPID_CAP);
+    let ept_needed = EPT_CAP_WALK_4 | EPT_CAP_WRITE_BACK | EPT_CAP_INVEPT | EPT_CAP_INVEPT_SINGLE;
+
+    procbased & PROC_SECONDARY_CONTROLS as u64 != 0
+        && secondary & (PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST) as u64 == (PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST) as u64
+        && ept & ept_needed == ept_needed
+}
+
+/// Enter VMX operation on this CPU, once
+fn enable_cpu(cpu: usize) -> Result<(), VirtError> {
+    if VMXON_REGIONS[cpu].load(Ordering::Relaxed) != 0 {
+        return Ok(());
+    }
+
+    let feature_control = read_msr(IA32_FEATURE_CONTROL);
+    if feature_control & FEATURE_CONTROL_LOCKED == 0 {
+        write_msr(IA32_FEATURE_CONTROL, feature_control | FEATURE_CONTROL_LOCKED | FEATURE_CONTROL_VMXON);
+    } else if feature_control & FEATURE_CONTROL_VMXON == 0 {
+        return Err(VirtError::DisabledByFirmware);
+    }
+
+    unsafe {
+        Cr0::write_raw((Cr0::read_raw() | read_msr(IA32_VMX_CR0_FIXED0)) & read_msr(IA32_VMX_CR0_FIXED1));
+        Cr4::write_raw((Cr4::read_raw() | CR4_VMXE | read_msr(IA32_VMX_CR4_FIXED0)) & read_msr(IA32_VMX_CR4_FIXED1));
+    }
+
+    let mut region = Page::new();
+    region.0[0..4].copy_from_slice(&revision_id().to_le_bytes());
+    let address = region.phys();
+    let flags: u64;
+    unsafe {
+        asm!(
+            "vmxon [{address}]",
+            "pushfq",
+            "pop {flags}",
+            address = in(reg) &address,
+            flags = out(reg) flags,
+        );
+    }
+    if flags & 0x41 != 0 {
+        return Err(VirtError::EnableFailed);
+    }
+
+    // Stays in use for as long as the CPU is in VMX operation
+    VMXON_REGIONS[cpu].store(Box::into_raw(region) as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+fn revision_id() -> u32 {
+    read_msr(IA32_VMX_BASIC) as u32 & 0x7fff_ffff
+}
+
+/// Controls adjusted to what the CPU allows: bits it requires set, bits
+/// it cannot do cleared
+fn adjust_controls(msr: u32, wanted: u32) -> u32 {
+    let msr = if read_msr(IA32_VMX_BASIC) & (1 << 55) != 0 && msr != IA32_VMX_PROCBASED_CTLS2 {
+        msr + IA32_VMX_TRUE_OFFSET
+    } else {
+        msr
+    };
+    let allowed = read_msr(msr);
+    (wanted | allowed as u32) & (allowed >> 32) as u32
+}
+
+/// CR0 bits the guest cannot choose: those VMX needs set (apart from PE
+/// and PG, which unrestricted guests may clear) or cleared
+fn cr0_host_owned() -> u64 {
+    let fixed0 = read_msr(IA32_VMX_CR0_FIXED0) & !(CR0_PE | CR0_PG);
+    (fixed0 | !read_msr(IA32_VMX_CR0_FIXED1)) & 0xffff_ffff
+}
+
+fn cr4_host_owned() -> u64 {
+    (read_msr(IA32_VMX_CR4_FIXED0) | !read_msr(IA32_VMX_CR4_FIXED1)) & 0xffff_ffff
+}
+
+fn guest_cr0(cr0: u64) -> u64 {
+    (cr0 | (read_msr(IA32_VMX_CR0_FIXED0) & !(CR0_PE | CR0_PG))) & read_msr(IA32_VMX_CR0_FIXED1)
+}
+
+fn guest_cr4(cr4: u64) -> u64 {
+    (cr4 | read_msr(IA32_VMX_CR4_FIXED0)) & read_msr(IA32_VMX_CR4_FIXED1)
+}
+
+fn read(field: VmcsField) -> u64 {
+    vmread(field).unwrap_or(0)
+}
+
+fn write(field: VmcsField, value: u64) {
+    // Fields are only written with the VMCS current and valid values
+    let _ = vmwrite(field, value);
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MsrEntry {
+    index: u32,
+    reserved: u32,
+    value: u64,
+}
+
+#[repr(C, align(16))]
+struct MsrArea([MsrEntry; SWITCHED_MSRS.len()]);
+
+impl MsrArea {
+    fn new() -> Box<Self> {
+        let mut area = Box::new(MsrArea([MsrEntry { index: 0, reserved: 0, value: 0 }; SWITCHED_MSRS.len()]));
+        for (entry, &msr) in area.0.iter_mut().zip(SWITCHED_MSRS.iter()) {
+            entry.index = msr;
+        }
+        area
+    }
+
+    fn get_mut(&mut self, msr: u32) -> Option<&mut u64> {
+        self.0.iter_mut().find(|entry| entry.index == msr).map(|entry| &mut entry.value)
+    }
+}
+
+/// An event for the next VM entry, as VM-entry interruption information
+#[derive(Clone, Copy)]
+struct Event {
+    info: u32,
+    error_code: u32,
+    instruction_len: u32,
+}
+
+pub struct VmxVcpu {
+    vmcs: Box<Page>,
+    vpid: u16,
+    // Guest values of SWITCHED_MSRS, loaded on entry and stored on exit
+    guest_msrs: Box<MsrArea>,
+    // Host values, loaded on exit
+    host_msrs: Box<MsrArea>,
+    initialized: bool,
+    launched: bool,
+    // Exception to inject, or an event an exit interrupted
+    event: Option<Event>,
+}
+
+impl VmxVcpu {
+    pub fn new(vpid: u16) -> Result<Self, VirtError> {
+        Ok(VmxVcpu {
+            vmcs: Page::new(),
+            vpid,
+            guest_msrs: MsrArea::new(),
+            host_msrs: MsrArea::new(),
+            initialized: false,
+            launched: false,
+            event: None,
+        })
+    }
+
+    fn load(&mut self) -> Result<(), VirtError> {
+        let address = self.vmcs.phys();
+        let flags: u64;
+        if !self.initialized {
+            self.vmcs.0[0..4].copy_from_slice(&revision_id().to_le_bytes());
+            unsafe {
+                asm!(
+                    "vmclear [{address}]",
+                    address = in(reg) &address,
+                );
+            }
+        }
+        unsafe {
+            asm!(
+                "vmptrld [{address}]",
+                "pushfq",
+                "pop {flags}",
+                address = in(reg) &address,
+                flags = out(reg) flags,
+            );
+        }
+        if flags & 0x41 != 0 {
+            return Err(VirtError::EnableFailed);
+        }
+
+        if !self.initialized {
+            self.setup()?;
+            self.initialized = true;
+        }
+        Ok(())
+    }
+
+    /// Controls and state that stay the same for the life of the VMCS
+    fn setup(&mut self) -> Result<(), VirtError> {
+        let secondary_wanted = PROC2_ENABLE_EPT
+            | PROC2_UNRESTRICTED_GUEST
+            | PROC2_ENABLE_VPID
+            | PROC2_ENABLE_RDTSCP
+            | PROC2_ENABLE_INVPCID;
+        let secondary = adjust_controls(IA32_VMX_PROCBASED_CTLS2, secondary_wanted);
+        if secondary & (PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST) != PROC2_ENABLE_EPT | PROC2_UNRESTRICTED_GUEST {
+            return Err(VirtError::NotSupported);
+        }
+
+        write(VmcsField::PinBasedVmExecControl, adjust_controls(
+            IA32_VMX_PINBASED_CTLS,
+            PIN_EXTERNAL_INTERRUPT_EXITING | PIN_NMI_EXITING,
+        ) as u64);
+        write(VmcsField::CpuBasedVmExecControl, adjust_controls(
+            IA32_VMX_PROCBASED_CTLS,
+            PROC_HLT_EXITING
+                | PROC_MWAIT_EXITING
+                | PROC_RDPMC_EXITING
+                | PROC_CR8_LOAD_EXITING
+                | PROC_CR8_STORE_EXITING
+                | PROC_UNCONDITIONAL_IO_EXITING
+                | PROC_MONITOR_EXITING
+                | PROC_SECONDARY_CONTROLS,
+        ) as u64);
+        write(VmcsField::SecondaryVmExecControl, secondary as u64);
+        write(VmcsField::VmExitControls, adjust_controls(
+            IA32_VMX_EXIT_CTLS,
+            EXIT_HOST_ADDRESS_SPACE_SIZE | EXIT_SAVE_PAT | EXIT_LOAD_PAT | EXIT_SAVE_EFER | EXIT_LOAD_EFER,
+        ) as u64);
+        write(VmcsField::VmEntryControls, adjust_controls(
+            IA32_VMX_ENTRY_CTLS,
+            ENTRY_LOAD_PAT | ENTRY_LOAD_EFER,
+        ) as u64);
+        if secondary & PROC2_ENABLE_VPID != 0 {
+            write(VmcsField::VirtualProcessorId, self.vpid as u64);
+        }
+
+        write(VmcsField::ExceptionBitmap, 0);
+        write(VmcsField::PageFaultErrorCodeMask, 0);
+        write(VmcsField::PageFaultErrorCodeMatch, 0);
+        write(VmcsField::Cr3TargetCount, 0);
+        write(VmcsField::Cr0GuestHostMask, cr0_host_owned());
+        write(VmcsField::Cr4GuestHostMask, cr4_host_owned());
+        write(VmcsField::VmcsLinkPointer, u64::MAX);
+
+        let guest_msrs = &*self.guest_msrs as *const MsrArea as u64;
+        let host_msrs = &*self.host_msrs as *const MsrArea as u64;
+        let count = SWITCHED_MSRS.len() as u64;
+        write(VmcsField::VmEntryMsrLoadAddr, guest_msrs);
+        write(VmcsField::VmEntryMsrLoadCount, count);
+        write(VmcsField::VmExitMsrStoreAddr, guest_msrs);
+        write(VmcsField::VmExitMsrStoreCount, count);
+        write(VmcsField::VmExitMsrLoadAddr, host_msrs);
+        write(VmcsField::VmExitMsrLoadCount, count);
+
+        write(VmcsField::GuestActivityState, 0);
+        write(VmcsField::GuestInterruptibilityInfo, 0);
+        write(VmcsField::GuestPendingDbgExceptions, 0);
+        write(VmcsField::GuestDr7, 0x400);
+        write(VmcsField::GuestIa32Debugctl, 0);
+        write(VmcsField::GuestIa32Pat, 0x0007_0406_0007_0406);
+        write(VmcsField::GuestSysenterCs, 0);
+        write(VmcsField::GuestSysenterEsp, 0);
+        write(VmcsField::GuestSysenterEip, 0);
+        Ok(())
+    }
+
+    /// Host state as the kernel has it now; restored on every VM exit
+    fn write_host_state(&mut self) {
+        let cr3: u64;
+        let (cs, ss, ds, es, fs, gs, tr): (u16, u16, u16, u16, u16, u16, u16);
+        unsafe {
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+            asm!(
+                "mov {0:x}, cs",
+                "mov {1:x}, ss",
+                "mov {2:x}, ds",
+                "mov {3:x}, es",
+                "mov {4:x}, fs",
+                "mov {5:x}, gs",
+                "str {6:x}",
+                out(reg) cs, out(reg) ss, out(reg) ds, out(reg) es, out(reg) fs, out(reg) gs, out(reg) tr,
+                options(nomem, nostack),
+            );
+        }
+        let gdt = x86_64::instructions::tables::sgdt();
+        let idt = x86_64::instructions::tables::sidt();
+
+        write(VmcsField::HostCr0, Cr0::read_raw());
+        write(VmcsField::HostCr3, cr3);
+        write(VmcsField::HostCr4, Cr4::read_raw());
+        write(VmcsField::HostCsSelector, (cs & !7) as u64);
+        write(VmcsField::HostSsSelector, (ss & !7) as u64);
+        write(VmcsField::HostDsSelector, (ds & !7) as u64);
+        write(VmcsField::HostEsSelector, (es & !7) as u64);
+        write(VmcsField::HostFsSelector, (fs & !7) as u64);
+        write(VmcsField::HostGsSelector, (gs & !7) as u64);
+        write(VmcsField::HostTrSelector, (tr & !7) as u64);
+        write(VmcsField::HostFsBase, read_msr(MSR_FS_BASE));
+        write(VmcsField::HostGsBase, read_msr(MSR_GS_BASE));
+        write(VmcsField::HostTrBase, tss_base(gdt.base.as_u64(), tr));
+        write(VmcsField::HostGdtrBase, gdt.base.as_u64());
+        write(VmcsField::HostIdtrBase, idt.base.as_u64());
+        write(VmcsField::HostIa32SysenterCs, read_msr(MSR_SYSENTER_CS));
+        write(VmcsField::HostIa32SysenterEsp, read_msr(MSR_SYSENTER_ESP));
+        write(VmcsField::HostIa32SysenterEip, read_msr(MSR_SYSENTER_EIP));
+        write(VmcsField::HostIa32Efer, read_msr(MSR_EFER));
+        write(VmcsField::HostIa32Pat, read_msr(MSR_PAT));
+
+        for entry in self.host_msrs.0.iter_mut() {
+            entry.value = read_msr(entry.index);
+        }
+    }
+
+    pub fn run(&mut self, state: &mut VcpuState, vm: &Vm) -> Result<Option<Exit>, VirtError> {
+        let cpu = current_cpu();
+        enable_cpu(cpu)?;
+        self.load()?;
+        self.write_host_state();
+
+        {
+            let memory = vm.memory.lock();
+            write(VmcsField::EptPointer, memory.root());
+            if state.memory_generation != memory.generation {
+                invept(memory.root());
+                state.memory_generation = memory.generation;
+            }
+        }
+
+        if state.sregs_dirty {
+            self.write_sregs(&state.sregs);
+            state.sregs_dirty = false;
+        }
+        write(VmcsField::GuestRsp, state.regs.rsp);
+        write(VmcsField::GuestRip, state.regs.rip);
+        write(VmcsField::GuestRflags, state.regs.rflags);
+        self.inject_events(state);
+
+        let failed = unsafe {
+            asm!("mov cr2, {}", in(reg) state.sregs.cr2, options(nostack));
+            let failed = vmx_enter(&mut state.regs, self.launched as u64);
+            asm!("mov {}, cr2", out(reg) state.sregs.cr2, options(nostack));
+            failed
+        };
+        if failed != 0 {
+            return Ok(Some(Exit::FailEntry(read(VmcsField::VmInstructionError))));
+        }
+        self.launched = true;
+
+        state.regs.rsp = read(VmcsField::GuestRsp);
+        state.regs.rip = read(VmcsField::GuestRip);
+        state.regs.rflags = read(VmcsField::GuestRflags);
+        self.read_sregs(&mut state.sregs);
+
+        // An event whose delivery the exit cut short is delivered again
+        let vectoring = read(VmcsField::IdtVectoringInfoField) as u32;
+        if vectoring & EVENT_VALID != 0 {
+            self.event = Some(Event {
+                info: vectoring & !0x7fff_f000,
+                error_code: read(VmcsField::IdtVectoringErrorCode) as u32,
+                instruction_len: read(VmcsField::VmExitInstructionLen) as u32,
+            });
+        }
+
+        self.handle_exit(state, vm)
+    }
+
+    fn inject_events(&mut self, state: &mut VcpuState) {
+        let mut controls = read(VmcsField::CpuBasedVmExecControl) as u32 & !PROC_INTERRUPT_WINDOW_EXITING;
+
+        if let Some(event) = self.event.take() {
+            write(VmcsField::VmEntryIntrInfoField, event.info as u64);
+            write(VmcsField::VmEntryExceptionErrorCode, event.error_code as u64);
+            write(VmcsField::VmEntryInstructionLen, event.instruction_len as u64);
+            if state.pending_interrupt.is_some() {
+                controls |= PROC_INTERRUPT_WINDOW_EXITING;
+            }
+        } else if let Some(vector) = state.pending_interrupt {
+            let shadow = read(VmcsField::GuestInterruptibilityInfo) & INTERRUPTIBILITY_SHADOW != 0;
+            if state.can_inject(shadow) {
+                write(VmcsField::VmEntryIntrInfoField, (EVENT_VALID | EVENT_TYPE_EXTERNAL | vector as u32) as u64);
+                state.pending_interrupt = None;
+            } else {
+                // Exit as soon as the guest can take it
+                controls |= PROC_INTERRUPT_WINDOW_EXITING;
+            }
+        }
+
+        write(VmcsField::CpuBasedVmExecControl, controls as u64);
+    }
+
+    fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) {
+        let mut info = EVENT_VALID | EVENT_TYPE_HARDWARE_EXCEPTION | vector as u32;
+        if error_code.is_some() {
+            info |= EVENT_DELIVER_ERROR_CODE;
+        }
+        self.event = Some(Event {
+            info,
+            error_code: error_code.unwrap_or(0),
+            instruction_len: 0,
+        });
+    }
+
+    fn skip_instruction(&mut self, state: &mut VcpuState) {
+        state.regs.rip += read(VmcsField::VmExitInstructionLen);
+        // The skipped instruction ends any STI or MOV SS shadow
+        let interruptibility = read(VmcsField::GuestInterruptibilityInfo);
+        write(VmcsField::GuestInterruptibilityInfo, interruptibility & !INTERRUPTIBILITY_SHADOW);
+    }
+
+    fn handle_exit(&mut self, state: &mut VcpuState, vm: &Vm) -> Result<Option<Exit>, VirtError> {
+        let reason = read(VmcsField::VmExitReason) as u32;
+        if reason & EXIT_REASON_ENTRY_FAILURE != 0 {
+            return Ok(Some(Exit::FailEntry(reason as u64 & 0xffff)));
+        }
+        let qualification = read(VmcsField::ExitQualification);
+
+        match reason & 0xffff {
+            EXIT_REASON_EXCEPTION_NMI => {
+                // Only NMIs exit here; they were meant for the host
+                let info = read(VmcsField::VmExitIntrInfo) as u32;
+                if info & EVENT_TYPE_MASK == EVENT_TYPE_NMI {
+                    unsafe { asm!("int 2") };
+                }
+                Ok(None)
+            }
+            EXIT_REASON_EXTERNAL_INTERRUPT => {
+                // Still pending at the host's interrupt controller
+                unsafe { asm!("sti", "nop", "cli") };
+                Ok(None)
+            }
+            EXIT_REASON_TRIPLE_FAULT => Ok(Some(Exit::Shutdown)),
+            EXIT_REASON_INIT | EXIT_REASON_SIPI | EXIT_REASON_INTERRUPT_WINDOW => Ok(None),
+            EXIT_REASON_CPUID => {
+                let [eax, ebx, ecx, edx] = guest_cpuid(state.regs.rax as u32, state.regs.rcx as u32);
+                state.regs.rax = eax as u64;
+                state.regs.rbx = ebx as u64;
+                state.regs.rcx = ecx as u64;
+                state.regs.rdx = edx as u64;
+                self.skip_instruction(state);
+                Ok(None)
+            }
+            EXIT_REASON_HLT => {
+                self.skip_instruction(state);
+                Ok(Some(Exit::Hlt))
+            }
+            EXIT_REASON_INVD => {
+                self.skip_instruction(state);
+                Ok(None)
+            }
+            EXIT_REASON_RDPMC => {
+                // The PMU is hidden from guests
+                self.inject_exception(VECTOR_GP, Some(0));
+                Ok(None)
+            }
+            EXIT_REASON_VMCALL..=EXIT_REASON_VMXON
+            | EXIT_REASON_MWAIT
+            | EXIT_REASON_MONITOR
+            | EXIT_REASON_INVEPT
+            | EXIT_REASON_INVVPID
+            | EXIT_REASON_XSETBV => {
+                // Not offered to guests
+                self.inject_exception(VECTOR_UD, None);
+                Ok(None)
+            }
+            EXIT_REASON_CR_ACCESS => self.handle_cr_access(state, qualification),
+            EXIT_REASON_IO_INSTRUCTION => {
+                // String and REP I/O would need the guest's segments and
+                // page tables walked
+                if qualification & (1 << 4) != 0 {
+                    return Ok(Some(Exit::EmulationFailure));
+                }
+                let size = (qualification & 0x7) as u8 + 1;
+                let write = qualification & (1 << 3) == 0;
+                let port = (qualification >> 16) as u16;
+                self.skip_instruction(state);
+                Ok(Some(Exit::Io { port, size, write, data: state.regs.rax as u32 }))
+            }
+            EXIT_REASON_RDMSR => {
+                let value = self.read_guest_msr(state, state.regs.rcx as u32);
+                state.regs.set_edx_eax(value);
+                self.skip_instruction(state);
+                Ok(None)
+            }
+            EXIT_REASON_WRMSR => {
+                let value = state.regs.edx_eax();
+                self.write_guest_msr(state, state.regs.rcx as u32, value);
+                self.skip_instruction(state);
+                Ok(None)
+            }
+            EXIT_REASON_EPT_VIOLATION => {
+                let address = read(VmcsField::GuestPhysicalAddress);
+                let write = qualification & (1 << 1) != 0;
+                match vm.memory.lock().handle_fault(address, write)? {
+                    Fault::Mapped => Ok(None),
+                    Fault::Mmio => Ok(Some(Exit::Mmio { address, write })),
+                }
+            }
+            other => Ok(Some(Exit::Unknown(other as u64))),
+        }
+    }
+
+    fn handle_cr_access(&mut self, state: &mut VcpuState, qualification: u64) -> Result<Option<Exit>, VirtError> {
+        let cr = qualification & 0xf;
+        let access = (qualification >> 4) & 0x3;
+        let gpr = (qualification >> 8) & 0xf;
+
+        match (access, cr) {
+            // MOV to CR0, CR4 and CR8
+            (0, 0) => {
+                if !self.set_cr0(state, state.regs.gpr(gpr)) {
+                    self.inject_exception(VECTOR_GP, Some(0));
+                    return Ok(None);
+                }
+            }
+            (0, 4) => {
+                let cr4 = state.regs.gpr(gpr);
+                if cr4 & CR4_VMXE != 0 {
+                    self.inject_exception(VECTOR_GP, Some(0));
+                    return Ok(None);
+                }
+                write(VmcsField::GuestCr4, guest_cr4(cr4));
+                write(VmcsField::Cr4ReadShadow, cr4);
+                state.sregs.cr4 = cr4;
+            }
+            (0, 8) => state.sregs.cr8 = state.regs.gpr(gpr) & 0xf,
+            // MOV from CR8
+            (1, 8) => state.regs.set_gpr(gpr, state.sregs.cr8),
+            _ => return Ok(Some(Exit::Unknown(EXIT_REASON_CR_ACCESS as u64))),
+        }
+        self.skip_instruction(state);
+        Ok(None)
+    }
+
+    /// Guest write to CR0, entering or leaving long mode if paging changes
+    /// with EFER.LME set. False if the value is invalid.
+    fn set_cr0(&mut self, state: &mut VcpuState, cr0: u64) -> bool {
+        if cr0 & CR0_PG != 0 && cr0 & CR0_PE == 0 {
+            return false;
+        }
+
+        let old = state.sregs.cr0;
+        let mut efer = state.sregs.efer;
+        if cr0 & CR0_PG != 0 && old & CR0_PG == 0 && efer & EFER_LME != 0 {
+            efer |= EFER_LMA;
+        } else if cr0 & CR0_PG == 0 && old & CR0_PG != 0 {
+            efer &= !EFER_LMA;
+        }
+        if efer != state.sregs.efer {
+            state.sregs.efer = efer;
+            self.write_efer(efer);
+        }
+
+        write(VmcsField::GuestCr0, guest_cr0(cr0));
+        write(VmcsField::Cr0ReadShadow, cr0);
+        state.sregs.cr0 = cr0;
+        true
+    }
+
+    fn write_efer(&mut self, efer: u64) {
+        let controls = read(VmcsField::VmEntryControls) as u32 & !ENTRY_IA32E_MODE_GUEST;
+        let ia32e = if efer & EFER_LMA != 0 { ENTRY_IA32E_MODE_GUEST } else { 0 };
+        write(VmcsField::VmEntryControls, (controls | ia32e) as u64);
+        write(VmcsField::GuestIa32Efer, efer);
+    }
+
+    fn read_guest_msr(&mut self, state: &VcpuState, msr: u32) -> u64 {
+        match msr {
+            MSR_EFER => read(VmcsField::GuestIa32Efer),
+            MSR_FS_BASE => read(VmcsField::GuestFsBase),
+            MSR_GS_BASE => read(VmcsField::GuestGsBase),
+            MSR_SYSENTER_CS => read(VmcsField::GuestSysenterCs),
+            MSR_SYSENTER_ESP => read(VmcsField::GuestSysenterEsp),
+            MSR_SYSENTER_EIP => read(VmcsField::GuestSysenterEip),
+            MSR_PAT => read(VmcsField::GuestIa32Pat),
+            _ => match self.guest_msrs.get_mut(msr) {
+                Some(value) => *value,
+                None => state.read_msr(msr),
+            },
+        }
+    }
+
+    fn write_guest_msr(&mut self, state: &mut VcpuState, msr: u32, value: u64) {
+        match msr {
+            MSR_EFER => {
+                // LMA follows CR0.PG; the guest cannot set it directly
+                let efer = (value & !EFER_LMA) | (state.sregs.efer & EFER_LMA);
+                state.sregs.efer = efer;
+                self.write_efer(efer);
+            }
+            MSR_FS_BASE => write(VmcsField::GuestFsBase, value),
+            MSR_GS_BASE => write(VmcsField::GuestGsBase, value),
+            MSR_SYSENTER_CS => write(VmcsField::GuestSysenterCs, value),
+            MSR_SYSENTER_ESP => write(VmcsField::GuestSysenterEsp, value),
+            MSR_SYSENTER_EIP => write(VmcsField::GuestSysenterEip, value),
+            MSR_PAT => write(VmcsField::GuestIa32Pat, value),
+            _ => match self.guest_msrs.get_mut(msr) {
+                Some(slot) => *slot = value,
+                None => state.write_msr(msr, value),
+            },
+        }
+    }
+
+    fn write_sregs(&mut self, sregs: &Sregs) {
+        use VmcsField::*;
+
+        let segments = [
+            (&sregs.es, GuestEsSelector, GuestEsBase, GuestEsLimit, GuestEsArBytes),
+            (&sregs.cs, GuestCsSelector, GuestCsBase, GuestCsLimit, GuestCsArBytes),
+            (&sregs.ss, GuestSsSelector, GuestSsBase, GuestSsLimit, GuestSsArBytes),
+            (&sregs.ds, GuestDsSelector, GuestDsBase, GuestDsLimit, GuestDsArBytes),
+            (&sregs.fs, GuestFsSelector, GuestFsBase, GuestFsLimit, GuestFsArBytes),
+            (&sregs.gs, GuestGsSelector, GuestGsBase, GuestGsLimit, GuestGsArBytes),
+            (&sregs.ldt, GuestLdtrSelector, GuestLdtrBase, GuestLdtrLimit, GuestLdtrArBytes),
+            (&sregs.tr, GuestTrSelector, GuestTrBase, GuestTrLimit, GuestTrArBytes),
+        ];
+        for (segment, selector, base, limit, access) in segments {
+            write(selector, segment.selector as u64);
+            write(base, segment.base);
+            write(limit, segment.limit as u64);
+            write(access, access_rights(segment) as u64);
+        }
+        write(GuestGdtrBase, sregs.gdt.base);
+        write(GuestGdtrLimit, sregs.gdt.limit as u64);
+        write(GuestIdtrBase, sregs.idt.base);
+        write(GuestIdtrLimit, sregs.idt.limit as u64);
+
+        write(GuestCr0, guest_cr0(sregs.cr0));
+        write(Cr0ReadShadow, sregs.cr0);
+        write(GuestCr3, sregs.cr3);
+        write(GuestCr4, guest_cr4(sregs.cr4));
+        write(Cr4ReadShadow, sregs.cr4);
+        self.write_efer(sregs.efer);
+    }
+
+    fn read_sregs(&self, sregs: &mut Sregs) {
+        use VmcsField::*;
+
+        let segments = [
+            (&mut sregs.es, GuestEsSelector, GuestEsBase, GuestEsLimit, GuestEsArBytes),
+            (&mut sregs.cs, GuestCsSelector, GuestCsBase, GuestCsLimit, GuestCsArBytes),
+            (&mut sregs.ss, GuestSsSelector, GuestSsBase, GuestSsLimit, GuestSsArBytes),
+            (&mut sregs.ds, GuestDsSelector, GuestDsBase, GuestDsLimit, GuestDsArBytes),
+            (&mut sregs.fs, GuestFsSelector, GuestFsBase, GuestFsLimit, GuestFsArBytes),
+            (&mut sregs.gs, GuestGsSelector, GuestGsBase, GuestGsLimit, GuestGsArBytes),
+            (&mut sregs.ldt, GuestLdtrSelector, GuestLdtrBase, GuestLdtrLimit, GuestLdtrArBytes),
+            (&mut sregs.tr, GuestTrSelector, GuestTrBase, GuestTrLimit, GuestTrArBytes),
+        ];
+        for (segment, selector, base, limit, access) in segments {
+            *segment = segment_from(read(selector) as u16, read(base), read(limit) as u32, read(access) as u32);
+        }
+        sregs.gdt.base = read(GuestGdtrBase);
+        sregs.gdt.limit = read(GuestGdtrLimit) as u16;
+        sregs.idt.base = read(GuestIdtrBase);
+        sregs.idt.limit = read(GuestIdtrLimit) as u16;
+
+        // Host-owned CR0 and CR4 bits read as the guest last wrote them
+        let cr0_mask = cr0_host_owned();
+        let cr4_mask = cr4_host_owned();
+        sregs.cr0 = (read(GuestCr0) & !cr0_mask) | (read(Cr0ReadShadow) & cr0_mask);
+        sregs.cr3 = read(GuestCr3);
+        sregs.cr4 = (read(GuestCr4) & !cr4_mask) | (read(Cr4ReadShadow) & cr4_mask);
+        sregs.efer = read(GuestIa32Efer);
+    }
+}
+
+impl Drop for VmxVcpu {
+    fn drop(&mut self) {
+        // Flush the VMCS out of the CPU's cache before its memory is reused
+        if self.initialized && VMXON_REGIONS[current_cpu()].load(Ordering::Relaxed) != 0 {
+            let address = self.vmcs.phys();
+            unsafe {
+                asm!(
+                    "vmclear [{address}]",
+                    address = in(reg) &address,
+                );
+            }
+        }
+    }
+}
+
+/// VMX access rights of a segment
+fn access_rights(segment: &Segment) -> u32 {
+    if segment.unusable != 0 {
+        return 1 << 16;
+    }
+    (segment.type_ as u32 & 0xf)
+        | (segment.s as u32 & 1) << 4
+        | (segment.dpl as u32 & 3) << 5
+        | (segment.present as u32 & 1) << 7
+        | (segment.avl as u32 & 1) << 12
+        | (segment.l as u32 & 1) << 13
+        | (segment.db as u32 & 1) << 14
+        | (segment.g as u32 & 1) << 15
+}
+
+fn segment_from(selector: u16, base: u64, limit: u32, access: u32) -> Segment {
+    Segment {
+        base,
+        limit,
+        selector,
+        type_: (access & 0xf) as u8,
+        s: (access >> 4 & 1) as u8,
+        dpl: (access >> 5 & 3) as u8,
+        present: (access >> 7 & 1) as u8,
+        avl: (access >> 12 & 1) as u8,
+        l: (access >> 13 & 1) as u8,
+        db: (access >> 14 & 1) as u8,
+        g: (access >> 15 & 1) as u8,
+        unusable: (access >> 16 & 1) as u8,
+        padding: 0,
+    }
+}
+
+/// Base of the TSS that `selector` names in the GDT at `gdt`
+fn tss_base(gdt: u64, selector: u16) -> u64 {
+    let descriptor = gdt + (selector & !7) as u64;
+    let (low, high) = unsafe { (*(descriptor as *const u64), *((descriptor + 8) as *const u64)) };
+    ((low >> 16) & 0xff_ffff) | ((low >> 32) & 0xff00_0000) | (high & 0xffff_ffff) << 32
+}
+
+/// Drop cached guest-physical translations made through `eptp`
+fn invept(eptp: u64) {
+    let descriptor: [u64; 2] = [eptp, 0];
+    unsafe {
+        asm!(
+            "invept {kind}, [{descriptor}]",
+            kind = in(reg) 1u64,
+            descriptor = in(reg) descriptor.as_ptr(),
+        );
+    }
+}
+
+/// Enter the guest with the registers in `regs`, returning 0 after a VM
+/// exit has saved them back, or RFLAGS if VMLAUNCH or VMRESUME failed
+#[unsafe(naked)]
+unsafe extern "C" fn vmx_enter(regs: *mut Regs, launched: u64) -> u64 {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "push rdi",
+        // HOST_RIP and HOST_RSP: the exit path below, with the Regs pointer
+        // on top of the stack
+        "lea rax, [rip + 3f]",
+        "mov rdx, 0x6c16",
+        "vmwrite rdx, rax",
+        "mov rdx, 0x6c14",
+        "vmwrite rdx, rsp",
+        // MOVs leave the flags from this compare alone
+        "cmp rsi, 0",
+        "mov rax, [rdi]",
+        "mov rbx, [rdi + 8]",
+        "mov rcx, [rdi + 16]",
+        "mov rdx, [rdi + 24]",
+        "mov rsi, [rdi + 32]",
+        "mov rbp, [rdi + 56]",
+        "mov r8, [rdi + 64]",
+        "mov r9, [rdi + 72]",
+        "mov r10, [rdi + 80]",
+        "mov r11, [rdi + 88]",
+        "mov r12, [rdi + 96]",
+        "mov r13, [rdi + 104]",
+        "mov r14, [rdi + 112]",
+        "mov r15, [rdi + 120]",
+        "mov rdi, [rdi + 40]",
+        "jne 2f",
+        "vmlaunch",
+        "jmp 4f",
+        "2:",
+        "vmresume",
+        // Entry failed
+        "4:",
+        "pushfq",
+        "pop rax",
+        "add rsp, 8",
+        "jmp 5f",
+        // VM exit
+        "3:",
+        "push rdi",
+        "mov rdi, [rsp + 8]",
+        "mov [rdi], rax",
+        "mov [rdi + 8], rbx",
+        "mov [rdi + 16], rcx",
+        "mov [rdi + 24], rdx",
+        "mov [rdi + 32], rsi",
+        "mov [rdi + 56], rbp",
+        "mov [rdi + 64], r8",
+        "mov [rdi + 72], r9",
+        "mov [rdi + 80], r10",
+        "mov [rdi + 88], r11",
+        "mov [rdi + 96], r12",
+        "mov [rdi + 104], r13",
+        "mov [rdi + 112], r14",
+        "mov [rdi + 120], r15",
+        "pop rax",
+        "mov [rdi + 40], rax",
+        "add rsp, 8",
+        "xor eax, eax",
+        "5:",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
@@ -0,0 +1,249 @@
+//! Virtio devices on PCI
+//!
+//! The virtio 1.0 PCI transport: a device's configuration structures are
+//! found through vendor-specific capabilities pointing into its BARs,
+//! features are negotiated, and virtqueues are set up in memory both sides
+//! share. Devices that only speak legacy virtio, with registers in I/O
+//! space, are not supported; QEMU's transitional devices offer both.
+//!
+//! Interrupts stay off. Drivers put a request on a queue and wait for it
+//! to come back on the used ring, as the NVMe and AHCI drivers wait on
+//! their completion queues.
+
+pub mod queue;
+
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::pcie::{PciDevice, PCIE_CONTROLLER};
+use crate::time::clocksource::now_ns;
+
+pub use queue::Queue;
+
+pub const VENDOR: u16 = 0x1AF4;
+// Transitional devices are 0x1000 + type - 1, the rest 0x1040 + type
+const TRANSITIONAL_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+const MODERN_BASE: u16 = 0x1040;
+
+pub const TYPE_9P: u16 = 9;
+
+// Feature bits every device has
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// Device status
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+// Vendor capabilities, by the structure they point at
+const CAP_VENDOR: u8 = 0x09;
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+// The common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+// No MSI-X vector; the queues are polled
+const NO_VECTOR: u16 = 0xFFFF;
+const RESET_TIMEOUT_MS: u64 = 100;
+
+/// The virtio device type of a PCI function, if it is a virtio device
+pub fn device_type(pci: &PciDevice) -> Option<u16> {
+    if pci.vendor_id != VENDOR {
+        return None;
+    }
+    match pci.device_id {
+        id if TRANSITIONAL_IDS.contains(&id) => {
+            // The subsystem device ID holds the type
+            Some(PCIE_CONTROLLER.lock().read16(pci.location, 0x2E))
+        }
+        id if id >= MODERN_BASE => Some(id - MODERN_BASE),
+        _ => None,
+    }
+}
+
+// A memory BAR's address, 64-bit ones taking the next BAR too
+fn bar_address(pci: &PciDevice, bar: usize) -> Option<u64> {
+    let low = *pci.bars.get(bar)?;
+    if low & 1 != 0 {
+        return None;
+    }
+    let mut base = (low & !0xF) as u64;
+    if (low >> 1) & 3 == 2 {
+        base |= (*pci.bars.get(bar + 1)? as u64) << 32;
+    }
+    if base == 0 { None } else { Some(base) }
+}
+
+/// A device's configuration structures, as virtual addresses
+pub struct Transport {
+    common: u64,
+    notify: u64,
+    notify_multiplier: u32,
+    device: u64,
+}
+
+impl Transport {
+    /// Find the structures and enable the function
+    pub fn new(pci: &PciDevice) -> Result<Transport, &'static str> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+        for cap in pci.capabilities.iter().filter(|cap| cap.id == CAP_VENDOR && cap.data.len() >= 16) {
+            let Some(base) = bar_address(pci, cap.data[4] as usize) else {
+                continue;
+            };
+            let offset = u32::from_le_bytes([cap.data[8], cap.data[9], cap.data[10], cap.data[11]]);
+            let address = PHYS_MEM_OFFSET + base + offset as u64;
+            // The first of each kind is the one to use
+            match cap.data[3] {
+                CAP_COMMON => common = common.or(Some(address)),
+                CAP_NOTIFY if notify.is_none() => {
+                    notify = Some(address);
+                    notify_multiplier = PCIE_CONTROLLER.lock().read32(pci.location, cap.offset + 16);
+                }
+                CAP_ISR => isr = isr.or(Some(address)),
+                CAP_DEVICE => device = device.or(Some(address)),
+                _ => {}
+            }
+        }
+        // The ISR structure goes unused with interrupts off, but every
+        // modern device has one
+        let (Some(common), Some(notify), Some(_)) = (common, notify, isr) else {
+            return Err("not a virtio 1.0 device");
+        };
+        PCIE_CONTROLLER.lock().enable_device(pci);
+        Ok(Transport { common, notify, notify_multiplier, device: device.unwrap_or(0) })
+    }
+
+    fn read8(&self, offset: u64) -> u8 {
+        unsafe { read_volatile((self.common + offset) as *const u8) }
+    }
+
+    fn read16(&self, offset: u64) -> u16 {
+        unsafe { read_volatile((self.common + offset) as *const u16) }
+    }
+
+    fn read32(&self, offset: u64) -> u32 {
+        unsafe { read_volatile((self.common + offset) as *const u32) }
+    }
+
+    fn write8(&self, offset: u64, value: u8) {
+        unsafe { write_volatile((self.common + offset) as *mut u8, value) }
+    }
+
+    fn write16(&self, offset: u64, value: u16) {
+        unsafe { write_volatile((self.common + offset) as *mut u16, value) }
+    }
+
+    fn write32(&self, offset: u64, value: u32) {
+        unsafe { write_volatile((self.common + offset) as *mut u32, value) }
+    }
+
+    fn write64(&self, offset: u64, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+
+    /// Reset the device and agree on features: those in `wanted` that it
+    /// offers, along with VERSION_1. Queues are set up after this and
+    /// `start` called once they are.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.reset()?;
+        self.write8(COMMON_STATUS, STATUS_ACKNOWLEDGE);
+        self.write8(COMMON_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut offered = 0u64;
+        for select in 0..2 {
+            self.write32(COMMON_DEVICE_FEATURE_SELECT, select);
+            offered |= (self.read32(COMMON_DEVICE_FEATURE) as u64) << (32 * select);
+        }
+        if offered & F_VERSION_1 == 0 {
+            self.fail();
+            return Err("device does not offer virtio 1.0");
+        }
+        let features = offered & (wanted | F_VERSION_1);
+        for select in 0..2 {
+            self.write32(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.write32(COMMON_DRIVER_FEATURE, (features >> (32 * select)) as u32);
+        }
+        self.write8(COMMON_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.read8(COMMON_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err("device refused the features");
+        }
+        Ok(features)
+    }
+
+    /// Set up queue `index` with at most `max_size` entries
+    pub fn queue(&self, index: u16, max_size: u16) -> Result<Queue, &'static str> {
+        if index >= self.read16(COMMON_NUM_QUEUES) {
+            return Err("no such queue");
+        }
+        self.write16(COMMON_QUEUE_SELECT, index);
+        let size = self.read16(COMMON_QUEUE_SIZE);
+        if size == 0 {
+            return Err("queue not available");
+        }
+        // Sizes are powers of two, so the smaller one is too
+        let size = size.min(max_size);
+        let queue = Queue::new(index, size)?;
+        self.write16(COMMON_QUEUE_SIZE, size);
+        self.write16(COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.write64(COMMON_QUEUE_DESC, queue.descriptors());
+        self.write64(COMMON_QUEUE_DRIVER, queue.available());
+        self.write64(COMMON_QUEUE_DEVICE, queue.used());
+        let notify_offset = self.read16(COMMON_QUEUE_NOTIFY_OFF) as u64 * self.notify_multiplier as u64;
+        self.write16(COMMON_QUEUE_ENABLE, 1);
+        Ok(queue.with_notify(self.notify + notify_offset))
+    }
+
+    /// Tell the device the driver is ready
+    pub fn start(&self) {
+        self.write8(COMMON_STATUS, self.read8(COMMON_STATUS) | STATUS_DRIVER_OK);
+    }
+
+    /// Stop the device; it lets go of its queues
+    pub fn reset(&self) -> Result<(), &'static str> {
+        self.write8(COMMON_STATUS, 0);
+        let deadline = now_ns() + RESET_TIMEOUT_MS * 1_000_000;
+        while self.read8(COMMON_STATUS) != 0 {
+            if now_ns() >= deadline {
+                return Err("device did not reset");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn fail(&self) {
+        self.write8(COMMON_STATUS, self.read8(COMMON_STATUS) | STATUS_FAILED);
+    }
+
+    /// A byte of the device-specific configuration
+    pub fn config8(&self, offset: u64) -> u8 {
+        if self.device == 0 {
+            return 0;
+        }
+        unsafe { read_volatile((self.device + offset) as *const u8) }
+    }
+
+    pub fn config16(&self, offset: u64) -> u16 {
+        u16::from_le_bytes([self.config8(offset), self.config8(offset + 1)])
+    }
+}
@@ -0,0 +1,177 @@
+// Split virtqueues
+//
+// The descriptor table, the available ring the driver fills and the used
+// ring the device fills, in one run of frames. A request is a chain of
+// descriptors for buffers the device reads and then ones it writes.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use x86_64::structures::paging::PhysFrame;
+
+use crate::memory::frame_allocator::FRAME_ALLOCATOR;
+use crate::memory::PHYS_MEM_OFFSET;
+use crate::time::clocksource::now_ns;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// A buffer in a request: its physical address and length, and whether the
+/// device writes it
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub length: u32,
+    pub writable: bool,
+}
+
+pub struct Queue {
+    index: u16,
+    size: u16,
+    // Physical address of the descriptor table, and the frames in all
+    memory: u64,
+    frames: usize,
+    notify: u64,
+    free: Vec<u16>,
+    // The next available ring slot, and the used ring entry after the last
+    // one taken
+    next_available: u16,
+    last_used: u16,
+}
+
+impl Queue {
+    pub(super) fn new(index: u16, size: u16) -> Result<Queue, &'static str> {
+        let bytes = Self::used_offset(size) + 6 + 8 * size as usize;
+        let frames = bytes.div_ceil(4096);
+        let first = FRAME_ALLOCATOR.lock().allocate_contiguous(frames, None).ok_or("out of memory for a queue")?;
+        let memory = first.start_address().as_u64();
+        unsafe { core::ptr::write_bytes((PHYS_MEM_OFFSET + memory) as *mut u8, 0, frames * 4096) };
+        Ok(Queue {
+            index,
+            size,
+            memory,
+            frames,
+            notify: 0,
+            free: (0..size).rev().collect(),
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    pub(super) fn with_notify(mut self, notify: u64) -> Queue {
+        self.notify = notify;
+        self
+    }
+
+    fn available_offset(size: u16) -> usize {
+        DESCRIPTOR_SIZE * size as usize
+    }
+
+    // The used ring is 4-byte aligned
+    fn used_offset(size: u16) -> usize {
+        (Self::available_offset(size) + 6 + 2 * size as usize).next_multiple_of(4)
+    }
+
+    pub(super) fn descriptors(&self) -> u64 {
+        self.memory
+    }
+
+    pub(super) fn available(&self) -> u64 {
+        self.memory + Self::available_offset(self.size) as u64
+    }
+
+    pub(super) fn used(&self) -> u64 {
+        self.memory + Self::used_offset(self.size) as u64
+    }
+
+    fn pointer<T>(&self, address: u64) -> *mut T {
+        (PHYS_MEM_OFFSET + address) as *mut T
+    }
+
+    /// Hand the device a request, returning the head of its chain
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return Err("queue full");
+        }
+        let chain: Vec<u16> = (0..buffers.len()).filter_map(|_| self.free.pop()).collect();
+        for (position, (buffer, &descriptor)) in buffers.iter().zip(&chain).enumerate() {
+            let next = chain.get(position + 1);
+            let mut flags = if next.is_some() { DESC_F_NEXT } else { 0 };
+            if buffer.writable {
+                flags |= DESC_F_WRITE;
+            }
+            let entry = self.memory + (descriptor as usize * DESCRIPTOR_SIZE) as u64;
+            unsafe {
+                write_volatile(self.pointer::<u64>(entry), buffer.address);
+                write_volatile(self.pointer::<u32>(entry + 8), buffer.length);
+                write_volatile(self.pointer::<u16>(entry + 12), flags);
+                write_volatile(self.pointer::<u16>(entry + 14), next.copied().unwrap_or(0));
+            }
+        }
+        let available = self.available();
+        let slot = available + 4 + 2 * (self.next_available % self.size) as u64;
+        unsafe { write_volatile(self.pointer::<u16>(slot), chain[0]) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // The device must see the descriptors and the ring entry before the
+        // index that covers them, and the index before the notification
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.pointer::<u16>(available + 2), self.next_available) };
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.notify as *mut u16, self.index) };
+        Ok(chain[0])
+    }
+
+    /// A request the device has finished with: the head of its chain and
+    /// the bytes written
+    pub fn take_used(&mut self) -> Option<(u16, u32)> {
+        let used = self.used();
+        let index = unsafe { read_volatile(self.pointer::<u16>(used + 2)) };
+        if index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let entry = used + 4 + 8 * (self.last_used % self.size) as u64;
+        let (head, length) = unsafe {
+            (read_volatile(self.pointer::<u32>(entry)) as u16, read_volatile(self.pointer::<u32>(entry + 4)))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        // The chain's descriptors are free again
+        let mut descriptor = head;
+        loop {
+            self.free.push(descriptor);
+            let entry = self.memory + (descriptor as usize * DESCRIPTOR_SIZE) as u64;
+            let flags = unsafe { read_volatile(self.pointer::<u16>(entry + 12)) };
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            descriptor = unsafe { read_volatile(self.pointer::<u16>(entry + 14)) };
+        }
+        Some((head, length))
+    }
+
+    /// Wait for the request at `head`, returning the bytes written
+    pub fn wait(&mut self, head: u16, timeout_ms: u64) -> Result<u32, &'static str> {
+        let deadline = now_ns() + timeout_ms * 1_000_000;
+        loop {
+            match self.take_used() {
+                Some((done, length)) if done == head => return Ok(length),
+                Some(_) => continue,
+                None if now_ns() >= deadline => return Err("device did not answer"),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+impl Drop for Queue {
+    // The device is reset before its queues are dropped
+    fn drop(&mut self) {
+        let first = PhysFrame::containing_address(x86_64::PhysAddr::new(self.memory));
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        for frame in PhysFrame::range(first, first + self.frames as u64) {
+            allocator.deallocate_frame(frame);
+        }
+    }
+}
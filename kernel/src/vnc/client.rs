@@ -0,0 +1,464 @@
+// One client's connection
+//
+// The RFB handshake (versions 3.3, 3.7 and 3.8, VNC authentication
+// only), then the client's messages as they arrive, and framebuffer
+// updates when it has asked for one and what it was sent has drained.
+// Keys and pointer moves go to the input queue, typed characters to the
+// shell, and cut text both ways through the clipboard.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::input::{self, ABS_X, ABS_Y, BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_ABS, EV_REL, REL_WHEEL};
+use crate::net::tcp::{self, TcpState};
+use crate::time::clocksource::now_ns;
+
+use super::deflate::Deflater;
+use super::encoding::{self, Area, PixelFormat};
+use super::keysym;
+use super::screen::{Region, Screen};
+
+const VERSION: &[u8; 12] = b"RFB 003.008\n";
+const SECURITY_VNC: u8 = 2;
+// Most taken from the receive buffer at once
+const RECEIVE_CHUNK: usize = 4096;
+// Longest cut text taken from a client
+const MAX_CUT_TEXT: usize = 1 << 20;
+// Updates are sent no more often than this
+const UPDATE_INTERVAL_NS: u64 = 40_000_000;
+// A client has this long to get through the handshake
+const HANDSHAKE_TIMEOUT_NS: u64 = 30_000_000_000;
+
+enum Phase {
+    Version,
+    Security,
+    Challenge([u8; 16]),
+    Init,
+    Running,
+    // Closing once what is queued has gone, for the reason given
+    Closing(&'static str),
+}
+
+struct Request {
+    incremental: bool,
+    region: Region,
+}
+
+/// What the server hands every client as it polls them
+pub struct Context<'a> {
+    pub password: &'a str,
+    pub keyboard: usize,
+    pub pointer: usize,
+    /// The screen now, captured for the first client ready for an update
+    pub screen: &'a mut Option<Screen>,
+    /// Characters typed, for the shell once the server is unlocked
+    pub typed: &'a mut String,
+}
+
+pub struct Client {
+    pub id: u32,
+    conn: u64,
+    pub address: String,
+    pub connected_at: u64,
+    phase: Phase,
+    minor: u8,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    sent: usize,
+    pub bytes_sent: u64,
+    format: PixelFormat,
+    zrle: bool,
+    copy_rect: bool,
+    desktop_size: bool,
+    // The screen size the client was last told
+    size: (usize, usize),
+    request: Option<Request>,
+    // What the client's copy of the screen holds
+    shadow: Option<Screen>,
+    stream: Deflater,
+    last_update: u64,
+    control: bool,
+    buttons: u8,
+    clipboard: u32,
+    /// Set when the client asked to have the server to itself
+    pub exclusive: bool,
+}
+
+impl Client {
+    pub fn new(id: u32, conn: u64) -> Client {
+        let address = match tcp::peer(conn) {
+            Some((address, port)) => format!("{}:{}", address, port),
+            None => String::from("?"),
+        };
+        let mut client = Client {
+            id,
+            conn,
+            address,
+            connected_at: now_ns(),
+            phase: Phase::Version,
+            minor: 8,
+            input: Vec::new(),
+            output: Vec::new(),
+            sent: 0,
+            bytes_sent: 0,
+            format: PixelFormat::NATIVE,
+            zrle: false,
+            copy_rect: false,
+            desktop_size: false,
+            size: (0, 0),
+            request: None,
+            shadow: None,
+            stream: Deflater::new(),
+            last_update: 0,
+            control: false,
+            buttons: 0,
+            clipboard: crate::clipboard::sequence(),
+            exclusive: false,
+        };
+        client.output.extend_from_slice(VERSION);
+        client
+    }
+
+    pub fn authenticated(&self) -> bool {
+        matches!(self.phase, Phase::Init | Phase::Running)
+    }
+
+    /// The encoding updates go out in
+    pub fn encoding(&self) -> &'static str {
+        if self.zrle {
+            "ZRLE"
+        } else {
+            "Raw"
+        }
+    }
+
+    pub fn pixel_format(&self) -> String {
+        let format = &self.format;
+        let bits = |max: u16| 16 - max.leading_zeros();
+        format!(
+            "{}bpp {}{}{}{}",
+            format.bits_per_pixel,
+            bits(format.red_max),
+            bits(format.green_max),
+            bits(format.blue_max),
+            if format.big_endian { " BE" } else { "" }
+        )
+    }
+
+    /// Take what has arrived, answer it, and send what is queued; an
+    /// error ends the connection
+    pub fn poll(&mut self, context: &mut Context) -> Result<(), &'static str> {
+        match tcp::state(self.conn) {
+            Some(TcpState::Established) => {}
+            _ => return Err("connection closed"),
+        }
+        if !self.authenticated() && now_ns().saturating_sub(self.connected_at) > HANDSHAKE_TIMEOUT_NS {
+            return Err("handshake timed out");
+        }
+        if !matches!(self.phase, Phase::Closing(_)) {
+            let mut input = core::mem::take(&mut self.input);
+            input.extend_from_slice(&tcp::recv(self.conn, RECEIVE_CHUNK)?);
+            let mut used = 0;
+            while let Some(length) = self.message(&input[used..], context)? {
+                used += length;
+            }
+            input.drain(..used);
+            self.input = input;
+        }
+        if matches!(self.phase, Phase::Running) {
+            self.cut_text();
+            if self.sent == self.output.len() && now_ns().saturating_sub(self.last_update) >= UPDATE_INTERVAL_NS {
+                self.update(context)?;
+            }
+        }
+        self.flush();
+        match self.phase {
+            Phase::Closing(reason) if self.sent == self.output.len() => Err(reason),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.sent == self.output.len() {
+            self.output.clear();
+            self.sent = 0;
+            return;
+        }
+        let window = tcp::send_window(self.conn).min(self.output.len() - self.sent);
+        if window > 0 && tcp::send(self.conn, &self.output[self.sent..self.sent + window]).is_ok() {
+            self.sent += window;
+            self.bytes_sent += window as u64;
+        }
+    }
+
+    fn fail(&mut self, reason: &'static str) {
+        // 3.8 says why; the versions before just close
+        self.output.extend_from_slice(&1u32.to_be_bytes());
+        if self.minor >= 8 {
+            self.output.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+            self.output.extend_from_slice(reason.as_bytes());
+        }
+        self.phase = Phase::Closing(reason);
+    }
+
+    fn challenge(&mut self) {
+        let mut challenge = [0u8; 16];
+        crate::crypto::rng::fill_bytes(&mut challenge);
+        self.output.extend_from_slice(&challenge);
+        self.phase = Phase::Challenge(challenge);
+    }
+
+    // Handle the message at the start of `input`, if it is all there,
+    // returning how many bytes it took
+    fn message(&mut self, input: &[u8], context: &mut Context) -> Result<Option<usize>, &'static str> {
+        let u16_at = |at: usize| u16::from_be_bytes([input[at], input[at + 1]]) as usize;
+        let u32_at = |at: usize| u32::from_be_bytes([input[at], input[at + 1], input[at + 2], input[at + 3]]) as usize;
+        match self.phase {
+            Phase::Version => {
+                let Some(version) = input.get(..12) else { return Ok(None) };
+                if &version[..4] != b"RFB " {
+                    return Err("not an RFB client");
+                }
+                // Versions it does not know are taken as 3.3, as the
+                // protocol has it
+                self.minor = match &version[4..11] {
+                    b"003.007" => 7,
+                    b"003.008" => 8,
+                    _ => 3,
+                };
+                if self.minor >= 7 {
+                    self.output.extend_from_slice(&[1, SECURITY_VNC]);
+                    self.phase = Phase::Security;
+                } else {
+                    self.output.extend_from_slice(&(SECURITY_VNC as u32).to_be_bytes());
+                    self.challenge();
+                }
+                Ok(Some(12))
+            }
+            Phase::Security => {
+                let Some(&choice) = input.first() else { return Ok(None) };
+                if choice != SECURITY_VNC {
+                    self.fail("unsupported security type");
+                } else {
+                    self.challenge();
+                }
+                Ok(Some(1))
+            }
+            Phase::Challenge(challenge) => {
+                let Some(response) = input.get(..16) else { return Ok(None) };
+                let expected = super::des::response(context.password, &challenge);
+                if expected.iter().zip(response).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+                    crate::pr_info!("vnc: {} failed authentication", self.address);
+                    self.fail("authentication failed");
+                } else {
+                    self.output.extend_from_slice(&0u32.to_be_bytes());
+                    self.phase = Phase::Init;
+                }
+                Ok(Some(16))
+            }
+            Phase::Init => {
+                let Some(&shared) = input.first() else { return Ok(None) };
+                self.exclusive = shared == 0;
+                let screen = context.screen.get_or_insert_with(super::screen::capture);
+                self.size = (screen.width, screen.height);
+                let name = format!("ReactOS Rust ({})", crate::container::nsproxy::hostname(0));
+                self.output.extend_from_slice(&(screen.width as u16).to_be_bytes());
+                self.output.extend_from_slice(&(screen.height as u16).to_be_bytes());
+                self.output.extend_from_slice(&self.format.to_bytes());
+                self.output.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                self.output.extend_from_slice(name.as_bytes());
+                self.phase = Phase::Running;
+                crate::pr_info!("vnc: {} connected", self.address);
+                Ok(Some(1))
+            }
+            Phase::Running => {
+                let Some(&kind) = input.first() else { return Ok(None) };
+                let length = match kind {
+                    0 => 20,
+                    2 if input.len() >= 4 => 4 + 4 * u16_at(2),
+                    3 => 10,
+                    4 => 8,
+                    5 => 6,
+                    6 if input.len() >= 8 => {
+                        if u32_at(4) > MAX_CUT_TEXT {
+                            return Err("cut text too long");
+                        }
+                        8 + u32_at(4)
+                    }
+                    2 | 6 => return Ok(None),
+                    _ => return Err("unknown message"),
+                };
+                if input.len() < length {
+                    return Ok(None);
+                }
+                match kind {
+                    0 => {
+                        self.format = PixelFormat::parse(&input[4..20])?;
+                        self.shadow = None;
+                    }
+                    2 => {
+                        let encodings: Vec<i32> =
+                            (0..u16_at(2)).map(|i| u32_at(4 + 4 * i) as u32 as i32).collect();
+                        self.zrle = encodings.contains(&encoding::ZRLE);
+                        self.copy_rect = encodings.contains(&encoding::COPY_RECT);
+                        self.desktop_size = encodings.contains(&encoding::DESKTOP_SIZE);
+                    }
+                    3 => {
+                        let region = Region { x: u16_at(2), y: u16_at(4), width: u16_at(6), height: u16_at(8) };
+                        let incremental = input[1] != 0 && self.request.as_ref().map_or(true, |r| r.incremental);
+                        self.request = Some(Request { incremental, region });
+                    }
+                    4 => {
+                        let (down, keysym) = (input[1] != 0, u32_at(4) as u32);
+                        self.key(context, down, keysym);
+                    }
+                    5 => {
+                        let (buttons, x, y) = (input[1], u16_at(2), u16_at(4));
+                        self.pointer(context, buttons, x, y);
+                    }
+                    _ => {
+                        // Latin-1, with LF line breaks
+                        let text: String = input[8..length].iter().map(|&byte| byte as char).collect();
+                        crate::clipboard::set_text(&text);
+                        self.clipboard = crate::clipboard::sequence();
+                    }
+                }
+                Ok(Some(length))
+            }
+            Phase::Closing(_) => Ok(None),
+        }
+    }
+
+    fn key(&mut self, context: &mut Context, down: bool, keysym: u32) {
+        if matches!(keysym, keysym::CONTROL_L | keysym::CONTROL_R) {
+            self.control = down;
+        }
+        if let Some(code) = keysym::key_code(keysym) {
+            input::key(context.keyboard, code, down);
+            input::sync(context.keyboard);
+        }
+        if down {
+            if let Some(sequence) = keysym::terminal(keysym, self.control) {
+                context.typed.push_str(&sequence);
+            }
+        }
+    }
+
+    fn pointer(&mut self, context: &mut Context, buttons: u8, x: usize, y: usize) {
+        let device = context.pointer;
+        input::report(device, EV_ABS, ABS_X, x as i32);
+        input::report(device, EV_ABS, ABS_Y, y as i32);
+        // Bits for the left, middle and right buttons, then the wheel
+        let changed = buttons ^ self.buttons;
+        for (bit, code) in [(0, BTN_LEFT), (1, BTN_MIDDLE), (2, BTN_RIGHT)] {
+            if changed & 1 << bit != 0 {
+                input::key(device, code, buttons & 1 << bit != 0);
+            }
+        }
+        let pressed = changed & buttons;
+        if pressed & 1 << 3 != 0 {
+            input::report(device, EV_REL, REL_WHEEL, 1);
+        }
+        if pressed & 1 << 4 != 0 {
+            input::report(device, EV_REL, REL_WHEEL, -1);
+        }
+        input::sync(device);
+        self.buttons = buttons;
+    }
+
+    // Send the clipboard's text when it has changed since the client saw it
+    fn cut_text(&mut self) {
+        let sequence = crate::clipboard::sequence();
+        if sequence == self.clipboard {
+            return;
+        }
+        self.clipboard = sequence;
+        let Some(text) = crate::clipboard::text() else { return };
+        let latin1: Vec<u8> = text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect();
+        self.output.extend_from_slice(&[3, 0, 0, 0]);
+        self.output.extend_from_slice(&(latin1.len() as u32).to_be_bytes());
+        self.output.extend_from_slice(&latin1);
+    }
+
+    // Answer the update asked for, if there is anything to send
+    fn update(&mut self, context: &mut Context) -> Result<(), &'static str> {
+        let Some((incremental, area)) = self.request.as_ref().map(|r| (r.incremental, r.region)) else {
+            return Ok(());
+        };
+        let screen = context.screen.get_or_insert_with(super::screen::capture);
+        let mut out = Vec::new();
+        let mut rectangles = 0u16;
+
+        if (screen.width, screen.height) != self.size {
+            if !self.desktop_size {
+                return Err("screen size changed");
+            }
+            encoding::header(&mut out, 0, 0, screen.width, screen.height, encoding::DESKTOP_SIZE);
+            rectangles += 1;
+            self.size = (screen.width, screen.height);
+            self.shadow = None;
+        }
+
+        // The area asked for, within the screen
+        let (x, y) = (area.x.min(screen.width), area.y.min(screen.height));
+        let area = Region {
+            x,
+            y,
+            width: area.width.min(screen.width - x),
+            height: area.height.min(screen.height - y),
+        };
+        let shadow = match self.shadow.take() {
+            Some(shadow) if incremental && rectangles == 0 => shadow,
+            // All of the area is sent, as if the client had seen nothing
+            Some(mut shadow) => {
+                shadow.copy_from(&screen.unseen(), area);
+                shadow
+            }
+            None => screen.unseen(),
+        };
+        let mut shadow = shadow;
+
+        if self.copy_rect {
+            if let Some(scroll) = shadow.find_scroll(screen) {
+                encoding::header(&mut out, 0, scroll.to, screen.width, scroll.rows, encoding::COPY_RECT);
+                out.extend_from_slice(&0u16.to_be_bytes());
+                out.extend_from_slice(&(scroll.from as u16).to_be_bytes());
+                shadow.apply(scroll);
+                rectangles += 1;
+            }
+        }
+        for region in shadow.changes(screen, area) {
+            let area = Area {
+                x: region.x,
+                y: region.y,
+                width: region.width,
+                height: region.height,
+                pixels: &screen.pixels,
+                stride: screen.width,
+            };
+            if self.zrle {
+                encoding::zrle(&mut out, &area, &self.format, &mut self.stream);
+            } else {
+                encoding::raw(&mut out, &area, &self.format);
+            }
+            shadow.copy_from(screen, region);
+            rectangles += 1;
+        }
+        self.shadow = Some(shadow);
+        if rectangles == 0 {
+            // Nothing changed; the request waits until something does
+            return Ok(());
+        }
+        self.output.extend_from_slice(&[0, 0]);
+        self.output.extend_from_slice(&rectangles.to_be_bytes());
+        self.output.extend_from_slice(&out);
+        self.request = None;
+        self.last_update = now_ns();
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = tcp::close(self.conn);
+    }
+}
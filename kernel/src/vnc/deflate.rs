@@ -0,0 +1,170 @@
+// The zlib stream ZRLE rectangles are sent in
+//
+// A connection has one stream, which each rectangle continues and ends
+// with a sync flush so the client can inflate it as it comes. Blocks use
+// the fixed Huffman codes, with matches found by a hash chain. Matches
+// stay within one rectangle's data, though the stream would allow
+// reaching back into the ones before.
+
+use alloc::vec::Vec;
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// Candidates looked at for each position
+const MAX_CHAIN: usize = 32;
+const NO_POSITION: u32 = u32::MAX;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] =
+    [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    // Bits go in from the least significant, as deflate packs them
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go in from their most significant bit
+    fn code(&mut self, code: u32, length: u32) {
+        self.bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.bits(0, 8 - self.count);
+        }
+    }
+
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.literal(257 + code as u32);
+        self.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+        let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.code(code as u32, 5);
+        self.bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+    }
+}
+
+pub struct Deflater {
+    started: bool,
+}
+
+impl Deflater {
+    pub fn new() -> Deflater {
+        Deflater { started: false }
+    }
+
+    /// `data` as the next part of the stream, ending on a sync flush
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter { out: Vec::with_capacity(data.len() / 2 + 16), bits: 0, count: 0 };
+        if !core::mem::replace(&mut self.started, true) {
+            // 32K window, fastest compression
+            writer.out.extend_from_slice(&[0x78, 0x01]);
+        }
+        if !data.is_empty() {
+            // Not the final block, fixed codes
+            writer.bits(0b010, 3);
+            compress_block(&mut writer, data);
+            writer.literal(256);
+        }
+        // The sync flush: an empty stored block
+        writer.bits(0, 3);
+        writer.align();
+        writer.out.extend_from_slice(&[0, 0, 0xFF, 0xFF]);
+        writer.out
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    (value.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// Positions by the hash of their first three bytes, each leading to the
+// one before with the same hash
+struct Chains {
+    head: Vec<u32>,
+    previous: Vec<u32>,
+}
+
+impl Chains {
+    fn insert(&mut self, data: &[u8], position: usize) {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(&data[position..]);
+            self.previous[position] = self.head[h];
+            self.head[h] = position as u32;
+        }
+    }
+}
+
+fn compress_block(writer: &mut BitWriter, data: &[u8]) {
+    let mut chains =
+        Chains { head: alloc::vec![NO_POSITION; 1 << HASH_BITS], previous: alloc::vec![NO_POSITION; data.len()] };
+
+    let mut position = 0;
+    while position < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let limit = (data.len() - position).min(MAX_MATCH);
+            let mut candidate = chains.head[hash(&data[position..])];
+            let mut chain = 0;
+            while candidate != NO_POSITION && chain < MAX_CHAIN {
+                let start = candidate as usize;
+                if position - start > WINDOW {
+                    break;
+                }
+                let length =
+                    data[start..].iter().zip(&data[position..position + limit]).take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, position - start);
+                    if length == limit {
+                        break;
+                    }
+                }
+                candidate = chains.previous[start];
+                chain += 1;
+            }
+        }
+        if best_length >= MIN_MATCH {
+            writer.copy(best_length, best_distance);
+            for p in position..position + best_length {
+                chains.insert(data, p);
+            }
+            position += best_length;
+        } else {
+            writer.literal(data[position] as u32);
+            chains.insert(data, position);
+            position += 1;
+        }
+    }
+}
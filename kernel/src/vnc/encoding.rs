@@ -0,0 +1,289 @@
+// Pixel formats and the encodings rectangles are sent in
+//
+// The screen is kept as 0xAARRGGBB and turned into the client's pixel
+// format as it is encoded. Raw sends the pixels as they are. ZRLE splits
+// a rectangle into 64x64 tiles and sends each as the smallest of raw
+// pixels, a solid color, a packed palette, or runs of pixels or palette
+// entries, all through the connection's zlib stream.
+
+use alloc::vec::Vec;
+
+use super::deflate::Deflater;
+
+pub const RAW: i32 = 0;
+pub const COPY_RECT: i32 = 1;
+pub const ZRLE: i32 = 16;
+/// Pseudo-encoding: the client can take a change of screen size
+pub const DESKTOP_SIZE: i32 = -223;
+
+const TILE: usize = 64;
+// Largest palette a tile can have
+const MAX_PALETTE: usize = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8,
+    pub depth: u8,
+    pub big_endian: bool,
+    pub true_color: bool,
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// 32-bit little-endian 0x00RRGGBB, as the screen is kept
+    pub const NATIVE: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_color: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    pub fn parse(bytes: &[u8]) -> Result<PixelFormat, &'static str> {
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let format = PixelFormat {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            true_color: bytes[3] != 0,
+            red_max: u16_at(4),
+            green_max: u16_at(6),
+            blue_max: u16_at(8),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        };
+        if !matches!(format.bits_per_pixel, 8 | 16 | 32) {
+            return Err("pixel size not 8, 16 or 32 bits");
+        }
+        if !format.true_color {
+            return Err("color maps are not supported");
+        }
+        let fits = |max: u16, shift: u8| (max as u64) << shift <= u32::MAX as u64 >> (32 - format.bits_per_pixel);
+        if !fits(format.red_max, format.red_shift)
+            || !fits(format.green_max, format.green_shift)
+            || !fits(format.blue_max, format.blue_shift)
+        {
+            return Err("colors do not fit the pixel");
+        }
+        Ok(format)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0] = self.bits_per_pixel;
+        bytes[1] = self.depth;
+        bytes[2] = self.big_endian as u8;
+        bytes[3] = self.true_color as u8;
+        bytes[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        bytes[10] = self.red_shift;
+        bytes[11] = self.green_shift;
+        bytes[12] = self.blue_shift;
+        bytes
+    }
+
+    fn value(&self, pixel: u32) -> u32 {
+        let scale = |channel: u32, max: u16| (channel * max as u32 + 127) / 255;
+        scale(pixel >> 16 & 0xFF, self.red_max) << self.red_shift
+            | scale(pixel >> 8 & 0xFF, self.green_max) << self.green_shift
+            | scale(pixel & 0xFF, self.blue_max) << self.blue_shift
+    }
+
+    fn bytes(&self) -> usize {
+        self.bits_per_pixel as usize / 8
+    }
+
+    fn write(&self, out: &mut Vec<u8>, pixel: u32) {
+        let value = self.value(pixel);
+        let bytes = self.bytes();
+        if self.big_endian {
+            out.extend_from_slice(&value.to_be_bytes()[4 - bytes..]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes()[..bytes]);
+        }
+    }
+
+    // ZRLE sends 32-bit pixels whose colors fit in three of their bytes
+    // as just those three: which ones, counted from the least significant
+    fn compact(&self) -> Option<usize> {
+        if self.bits_per_pixel != 32 || self.depth > 24 {
+            return None;
+        }
+        let used = (self.red_max as u32) << self.red_shift
+            | (self.green_max as u32) << self.green_shift
+            | (self.blue_max as u32) << self.blue_shift;
+        if used & 0xFF00_0000 == 0 {
+            Some(0)
+        } else if used & 0xFF == 0 {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    fn write_compact(&self, out: &mut Vec<u8>, pixel: u32) {
+        match self.compact() {
+            Some(low) => {
+                let value = self.value(pixel) >> (8 * low);
+                if self.big_endian {
+                    out.extend_from_slice(&value.to_be_bytes()[1..]);
+                } else {
+                    out.extend_from_slice(&value.to_le_bytes()[..3]);
+                }
+            }
+            None => self.write(out, pixel),
+        }
+    }
+}
+
+/// A rectangle of the screen, and its pixels row after row
+pub struct Area<'a> {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: &'a [u32],
+    pub stride: usize,
+}
+
+impl Area<'_> {
+    fn row(&self, y: usize) -> &[u32] {
+        let start = (self.y + y) * self.stride + self.x;
+        &self.pixels[start..start + self.width]
+    }
+
+    fn part(&self, x: usize, y: usize, width: usize, height: usize) -> Area<'_> {
+        Area { x: self.x + x, y: self.y + y, width, height, pixels: self.pixels, stride: self.stride }
+    }
+}
+
+pub fn header(out: &mut Vec<u8>, x: usize, y: usize, width: usize, height: usize, encoding: i32) {
+    for value in [x, y, width, height] {
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    }
+    out.extend_from_slice(&encoding.to_be_bytes());
+}
+
+pub fn raw(out: &mut Vec<u8>, area: &Area, format: &PixelFormat) {
+    header(out, area.x, area.y, area.width, area.height, RAW);
+    out.reserve(area.width * area.height * format.bytes());
+    for y in 0..area.height {
+        for &pixel in area.row(y) {
+            format.write(out, pixel);
+        }
+    }
+}
+
+pub fn zrle(out: &mut Vec<u8>, area: &Area, format: &PixelFormat, stream: &mut Deflater) {
+    header(out, area.x, area.y, area.width, area.height, ZRLE);
+    let mut data = Vec::new();
+    for y in (0..area.height).step_by(TILE) {
+        for x in (0..area.width).step_by(TILE) {
+            let tile = area.part(x, y, TILE.min(area.width - x), TILE.min(area.height - y));
+            zrle_tile(&mut data, &tile, format);
+        }
+    }
+    let compressed = stream.compress(&data);
+    out.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    out.extend_from_slice(&compressed);
+}
+
+// A run's length less one, as bytes of 255 and a last one below it
+fn run_length(out: &mut Vec<u8>, length: usize) {
+    let mut rest = length - 1;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn zrle_tile(out: &mut Vec<u8>, tile: &Area, format: &PixelFormat) {
+    let pixel_size = if format.compact().is_some() { 3 } else { format.bytes() };
+    let pixels: Vec<u32> = (0..tile.height).flat_map(|y| tile.row(y).iter().map(|&p| p & 0xFF_FFFF)).collect();
+
+    // The colors, up to as many as a palette holds, and the runs
+    let mut palette: Vec<u32> = Vec::new();
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for &pixel in &pixels {
+        match runs.last_mut() {
+            Some((color, length)) if *color == pixel => *length += 1,
+            _ => {
+                runs.push((pixel, 1));
+                if palette.len() <= MAX_PALETTE && !palette.contains(&pixel) {
+                    palette.push(pixel);
+                }
+            }
+        }
+    }
+    if palette.len() == 1 {
+        out.push(1);
+        format.write_compact(out, palette[0]);
+        return;
+    }
+    let run_bytes = |length: usize| (length - 1) / 255 + 1;
+    let raw_cost = pixels.len() * pixel_size;
+    let plain_rle_cost: usize = runs.iter().map(|&(_, length)| pixel_size + run_bytes(length)).sum();
+    let (palette_rle_cost, packed_cost, bits) = if palette.len() <= MAX_PALETTE {
+        // A run of one is just its index
+        let runs_cost: usize =
+            runs.iter().map(|&(_, length)| if length == 1 { 1 } else { 1 + run_bytes(length) }).sum();
+        let bits = match palette.len() {
+            2 => 1,
+            3..=4 => 2,
+            _ => 4,
+        };
+        let packed = if palette.len() <= 16 { (tile.width * bits).div_ceil(8) * tile.height } else { usize::MAX };
+        let palette_cost = palette.len() * pixel_size;
+        (palette_cost + runs_cost, palette_cost.saturating_add(packed), bits)
+    } else {
+        (usize::MAX, usize::MAX, 0)
+    };
+
+    let index = |pixel: u32| palette.iter().position(|&color| color == pixel).unwrap();
+    let best = raw_cost.min(plain_rle_cost).min(palette_rle_cost).min(packed_cost);
+    if best == packed_cost {
+        out.push(palette.len() as u8);
+        palette.iter().for_each(|&color| format.write_compact(out, color));
+        for row in pixels.chunks(tile.width) {
+            let mut packed = alloc::vec![0u8; (tile.width * bits).div_ceil(8)];
+            for (x, &pixel) in row.iter().enumerate() {
+                let bit = x * bits;
+                packed[bit / 8] |= (index(pixel) as u8) << (8 - bits - bit % 8);
+            }
+            out.extend_from_slice(&packed);
+        }
+    } else if best == palette_rle_cost {
+        out.push(128 + palette.len() as u8);
+        palette.iter().for_each(|&color| format.write_compact(out, color));
+        for &(color, length) in &runs {
+            if length == 1 {
+                out.push(index(color) as u8);
+            } else {
+                out.push(128 | index(color) as u8);
+                run_length(out, length);
+            }
+        }
+    } else if best == plain_rle_cost {
+        out.push(128);
+        for &(color, length) in &runs {
+            format.write_compact(out, color);
+            run_length(out, length);
+        }
+    } else {
+        out.push(0);
+        pixels.iter().for_each(|&pixel| format.write_compact(out, pixel));
+    }
+}
@@ -0,0 +1,137 @@
+// X keysyms, which RFB key events carry
+//
+// A key is passed on twice: as a Linux key code to the input queue, for
+// what reads keys, and as the characters a terminal would send to the
+// shell, so a client can type at the prompt as the local keyboard does.
+// The RDP server goes the other way, from its clients' key codes to
+// keysyms, for the same characters.
+
+use alloc::string::String;
+
+pub const CONTROL_L: u32 = 0xFFE3;
+pub const CONTROL_R: u32 = 0xFFE4;
+
+// Latin-1 keysyms are the characters themselves; these are the rest used
+const BACKSPACE: u32 = 0xFF08;
+const TAB: u32 = 0xFF09;
+const RETURN: u32 = 0xFF0D;
+const ESCAPE: u32 = 0xFF1B;
+const HOME: u32 = 0xFF50;
+const LEFT: u32 = 0xFF51;
+const UP: u32 = 0xFF52;
+const RIGHT: u32 = 0xFF53;
+const DOWN: u32 = 0xFF54;
+const PAGE_UP: u32 = 0xFF55;
+const PAGE_DOWN: u32 = 0xFF56;
+const END: u32 = 0xFF57;
+const INSERT: u32 = 0xFF63;
+const KP_ENTER: u32 = 0xFF8D;
+const F1: u32 = 0xFFBE;
+const F12: u32 = 0xFFC9;
+pub const SHIFT_L: u32 = 0xFFE1;
+pub const SHIFT_R: u32 = 0xFFE2;
+const ALT_L: u32 = 0xFFE9;
+const ALT_R: u32 = 0xFFEA;
+const DELETE: u32 = 0xFFFF;
+
+// Keys other than the rows, F1 to F12 and the keypad, and their Linux
+// key codes
+const KEYS: [(u32, u16); 22] = [
+    (BACKSPACE, 14),
+    (TAB, 15),
+    (RETURN, 28),
+    (ESCAPE, 1),
+    (HOME, 102),
+    (LEFT, 105),
+    (UP, 103),
+    (RIGHT, 106),
+    (DOWN, 108),
+    (PAGE_UP, 104),
+    (PAGE_DOWN, 109),
+    (END, 107),
+    (INSERT, 110),
+    (KP_ENTER, 96),
+    (DELETE, 111),
+    (SHIFT_L, 42),
+    (SHIFT_R, 54),
+    (CONTROL_L, 29),
+    (CONTROL_R, 97),
+    (ALT_L, 56),
+    (ALT_R, 100),
+    (0x20, 57),
+];
+
+// Linux key codes of a US keyboard's rows, and what Shift gives on them
+const ROWS: [(&str, &str, u16); 4] = [
+    ("1234567890-=", "!@#$%^&*()_+", 2),
+    ("qwertyuiop[]", "QWERTYUIOP{}", 16),
+    ("asdfghjkl;'`", "ASDFGHJKL:\"~", 30),
+    ("\\zxcvbnm,./", "|ZXCVBNM<>?", 43),
+];
+
+/// The Linux key code of the key that gives `keysym`
+pub fn key_code(keysym: u32) -> Option<u16> {
+    if let Some(&(_, code)) = KEYS.iter().find(|&&(key, _)| key == keysym) {
+        return Some(code);
+    }
+    match keysym {
+        // F1 to F10, then F11 and F12 apart
+        F1..=F12 => match keysym - F1 {
+            n @ 0..=9 => Some(59 + n as u16),
+            n => Some(87 + (n - 10) as u16),
+        },
+        0x21..=0x7E => {
+            let c = keysym as u8 as char;
+            ROWS.iter().find_map(|&(plain, shifted, first)| {
+                let column = plain.find(c).or_else(|| shifted.find(c))?;
+                Some(first + column as u16)
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The keysym of the key with Linux key code `code`, as Shift changes it
+pub fn from_key_code(code: u16, shift: bool) -> Option<u32> {
+    if let Some(&(keysym, _)) = KEYS.iter().find(|&&(_, key)| key == code) {
+        return Some(keysym);
+    }
+    match code {
+        59..=68 => Some(F1 + (code - 59) as u32),
+        87 | 88 => Some(F1 + 10 + (code - 87) as u32),
+        _ => ROWS.iter().find_map(|&(plain, shifted, first)| {
+            let column = code.checked_sub(first)? as usize;
+            let row = if shift { shifted } else { plain };
+            row.as_bytes().get(column).map(|&c| c as u32)
+        }),
+    }
+}
+
+/// What a terminal sends for `keysym`, with Ctrl making letters control
+/// characters
+pub fn terminal(keysym: u32, control: bool) -> Option<String> {
+    let sequence = match keysym {
+        BACKSPACE => "\x08",
+        TAB => "\t",
+        RETURN | KP_ENTER => "\n",
+        ESCAPE => "\x1b",
+        UP => "\x1b[A",
+        DOWN => "\x1b[B",
+        RIGHT => "\x1b[C",
+        LEFT => "\x1b[D",
+        HOME => "\x1b[H",
+        END => "\x1b[F",
+        DELETE => "\x1b[3~",
+        PAGE_UP => "\x1b[5~",
+        PAGE_DOWN => "\x1b[6~",
+        0x20..=0x7E => {
+            let c = keysym as u8 as char;
+            if control && c.is_ascii_alphabetic() {
+                return Some(String::from((c.to_ascii_lowercase() as u8 - b'a' + 1) as char));
+            }
+            return Some(String::from(c));
+        }
+        _ => return None,
+    };
+    Some(String::from(sequence))
+}
@@ -0,0 +1,202 @@
+//! VNC server
+//!
+//! Remote framebuffer (RFB) access for managing a machine without a
+//! screen or keyboard of its own. The server listens on a TCP port and
+//! shows clients the compositor's screen, or the text console when the
+//! compositor is not running. Updates cover only what changed, as Raw,
+//! CopyRect for scrolled rows, or ZRLE. Clients' keys and pointer go to
+//! the input queue, and their typing to the shell.
+//!
+//! Clients must know the VNC password; the server does not start
+//! without one. It runs from the main loop, a poll at a time.
+
+pub mod client;
+pub mod deflate;
+pub mod des;
+pub mod encoding;
+pub mod keysym;
+pub mod screen;
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::boot::cmdline::Param;
+use crate::input::{self, DeviceKind};
+use crate::net::tcp;
+use crate::time::clocksource::now_ns;
+use client::{Client, Context};
+
+pub const DEFAULT_PORT: u16 = 5900;
+// Clients served at once; more are turned away
+const MAX_CLIENTS: usize = 4;
+const POLL_INTERVAL_NS: u64 = 10_000_000;
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+static VNC: Param<bool> = Param::new("vnc", false, "Start the VNC server at boot");
+static PORT: Param<i64> = Param::new("vnc.port", DEFAULT_PORT as i64, "Port the VNC server listens on");
+static PASSWORD_PARAM: Param<&str> = Param::new("vnc.password", "", "VNC password");
+
+struct Server {
+    port: u16,
+    keyboard: usize,
+    pointer: usize,
+    clients: Vec<Client>,
+    next_id: u32,
+}
+
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+static PASSWORD: Mutex<String> = Mutex::new(String::new());
+
+/// A client, as `status` lists it
+pub struct ClientInfo {
+    pub id: u32,
+    pub address: String,
+    pub connected_ns: u64,
+    pub authenticated: bool,
+    pub encoding: &'static str,
+    pub pixel_format: String,
+    pub bytes_sent: u64,
+}
+
+pub struct Status {
+    pub port: u16,
+    pub clients: Vec<ClientInfo>,
+}
+
+/// Start the server if the command line asks for it
+pub fn init() {
+    let password = PASSWORD_PARAM.get();
+    if !password.is_empty() {
+        let _ = set_password(password);
+    }
+    if !VNC.get() {
+        return;
+    }
+    let port = PORT.get();
+    let Ok(port) = u16::try_from(port) else {
+        crate::pr_warn!("vnc: {} is not a port", port);
+        return;
+    };
+    match start(port) {
+        Ok(()) => crate::pr_info!("vnc: listening on port {}", port),
+        Err(e) => crate::pr_warn!("vnc: {}", e),
+    }
+}
+
+/// Set the password clients must give; only the first eight characters count
+pub fn set_password(password: &str) -> Result<(), &'static str> {
+    if password.is_empty() {
+        return Err("the password cannot be empty");
+    }
+    *PASSWORD.lock() = String::from(password);
+    Ok(())
+}
+
+pub fn has_password() -> bool {
+    !PASSWORD.lock().is_empty()
+}
+
+pub fn start(port: u16) -> Result<(), &'static str> {
+    if !has_password() {
+        return Err("set a password first");
+    }
+    let mut server = SERVER.lock();
+    if server.is_some() {
+        return Err("already running");
+    }
+    tcp::listen(port)?;
+    *server = Some(Server {
+        port,
+        keyboard: input::register("VNC keyboard", DeviceKind::Keys),
+        pointer: input::register("VNC pointer", DeviceKind::Mouse),
+        clients: Vec::new(),
+        next_id: 1,
+    });
+    Ok(())
+}
+
+/// Stop listening and drop every client
+pub fn stop() -> Result<(), &'static str> {
+    let server = SERVER.lock().take().ok_or("not running")?;
+    tcp::unlisten(server.port);
+    input::unregister(server.keyboard);
+    input::unregister(server.pointer);
+    Ok(())
+}
+
+pub fn status() -> Option<Status> {
+    let server = SERVER.lock();
+    let server = server.as_ref()?;
+    let clients = server
+        .clients
+        .iter()
+        .map(|client| ClientInfo {
+            id: client.id,
+            address: client.address.clone(),
+            connected_ns: client.connected_at,
+            authenticated: client.authenticated(),
+            encoding: client.encoding(),
+            pixel_format: client.pixel_format(),
+            bytes_sent: client.bytes_sent,
+        })
+        .collect();
+    Some(Status { port: server.port, clients })
+}
+
+pub fn disconnect(id: u32) -> Result<(), &'static str> {
+    let mut server = SERVER.lock();
+    let server = server.as_mut().ok_or("not running")?;
+    let index = server.clients.iter().position(|client| client.id == id).ok_or("no such client")?;
+    let client = server.clients.remove(index);
+    crate::pr_info!("vnc: {} disconnected", client.address);
+    Ok(())
+}
+
+/// Take new connections and serve the clients; called from the main loop
+pub fn poll() {
+    let now = now_ns();
+    if now < NEXT_POLL.load(Ordering::Relaxed) {
+        return;
+    }
+    NEXT_POLL.store(now + POLL_INTERVAL_NS, Ordering::Relaxed);
+
+    let mut typed = String::new();
+    {
+        let mut server = SERVER.lock();
+        let Some(server) = server.as_mut() else { return };
+        while let Some(conn) = tcp::accept(server.port) {
+            if server.clients.len() >= MAX_CLIENTS {
+                let _ = tcp::close(conn);
+                continue;
+            }
+            server.clients.push(Client::new(server.next_id, conn));
+            server.next_id += 1;
+        }
+
+        let password = PASSWORD.lock().clone();
+        let mut screen = None;
+        let mut context = Context {
+            password: &password,
+            keyboard: server.keyboard,
+            pointer: server.pointer,
+            screen: &mut screen,
+            typed: &mut typed,
+        };
+        server.clients.retain_mut(|client| match client.poll(&mut context) {
+            Ok(()) => true,
+            Err(reason) => {
+                crate::pr_info!("vnc: {} disconnected: {}", client.address, reason);
+                false
+            }
+        });
+        // A client that did not ask to share has the server to itself
+        if let Some(id) = server.clients.iter_mut().find_map(|c| core::mem::take(&mut c.exclusive).then_some(c.id)) {
+            server.clients.retain(|client| client.id == id);
+        }
+    }
+    // The shell may want the server itself, so it hears the keys only
+    // once the server is unlocked
+    typed.chars().for_each(crate::cmd_shell::handle_keyboard_input);
+}
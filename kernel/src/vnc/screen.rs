@@ -0,0 +1,165 @@
+// What the server shows, and what changed since a client last saw it
+//
+// The compositor's screen while it runs; otherwise the text console,
+// drawn as a VGA card draws it: 8x16 cells in the sixteen CGA colors,
+// with the cursor as an underline.
+
+use alloc::vec::Vec;
+
+use crate::graphics::FramebufferOps;
+use crate::vga_buffer::{TEXT_HEIGHT, TEXT_WIDTH};
+
+const CELL_WIDTH: usize = 8;
+const CELL_HEIGHT: usize = 16;
+// Changes are looked for this many pixels square at a time
+const TILE: usize = 32;
+// A scroll is only sent as a copy if it saves this many rows
+const MIN_SCROLL_ROWS: usize = 16;
+// Rows looked at as where a scroll came from
+const MAX_CANDIDATES: usize = 64;
+
+const CGA: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555, 0x5555FF, 0x55FF55,
+    0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+#[derive(Clone)]
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A block of rows that moved: where they were, where they are, how many
+#[derive(Debug, Clone, Copy)]
+pub struct Scroll {
+    pub from: usize,
+    pub to: usize,
+    pub rows: usize,
+}
+
+pub fn capture() -> Screen {
+    let compositor = crate::graphics::compositor::COMPOSITOR.lock();
+    if let Some(compositor) = compositor.as_ref() {
+        let framebuffer = compositor.screen();
+        let (width, height) = (framebuffer.width(), framebuffer.height());
+        return Screen { width, height, pixels: framebuffer.buffer().to_vec() };
+    }
+    drop(compositor);
+    text_console()
+}
+
+fn text_console() -> Screen {
+    let cells = crate::vga_buffer::read_cells();
+    let cursor = crate::vga_buffer::cursor();
+    let (width, height) = (TEXT_WIDTH * CELL_WIDTH, TEXT_HEIGHT * CELL_HEIGHT);
+    let mut pixels = alloc::vec![0; width * height];
+    for (index, &(byte, attribute)) in cells.iter().enumerate() {
+        let (row, column) = (index / TEXT_WIDTH, index % TEXT_WIDTH);
+        let foreground = 0xFF00_0000 | CGA[attribute as usize & 0xF];
+        let background = 0xFF00_0000 | CGA[attribute as usize >> 4];
+        let glyph = crate::graphics::font::glyph(crate::fs::unicode::from_cp437(byte)).copied().unwrap_or([0; 8]);
+        for y in 0..CELL_HEIGHT {
+            // The 8x8 font, each row drawn twice
+            let mut bits = glyph[y / 2];
+            if cursor == Some((row, column)) && y >= CELL_HEIGHT - 2 {
+                bits = 0xFF;
+            }
+            let start = (row * CELL_HEIGHT + y) * width + column * CELL_WIDTH;
+            for (x, pixel) in pixels[start..start + CELL_WIDTH].iter_mut().enumerate() {
+                *pixel = if bits >> x & 1 != 0 { foreground } else { background };
+            }
+        }
+    }
+    Screen { width, height, pixels }
+}
+
+impl Screen {
+    /// A screen that differs from this one at every pixel, for a client
+    /// that has seen nothing yet
+    pub fn unseen(&self) -> Screen {
+        Screen { width: self.width, height: self.height, pixels: self.pixels.iter().map(|&pixel| !pixel).collect() }
+    }
+
+    fn row(&self, y: usize) -> &[u32] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    /// Take a region's pixels from `other`, of the same size
+    pub fn copy_from(&mut self, other: &Screen, region: Region) {
+        for y in region.y..region.y + region.height {
+            let start = y * self.width + region.x;
+            self.pixels[start..start + region.width].copy_from_slice(&other.pixels[start..start + region.width]);
+        }
+    }
+
+    pub fn apply(&mut self, scroll: Scroll) {
+        let width = self.width;
+        self.pixels.copy_within(scroll.from * width..(scroll.from + scroll.rows) * width, scroll.to * width);
+    }
+
+    /// The rows `new` has moved up or down from this screen, if moving
+    /// them saves enough of sending them
+    pub fn find_scroll(&self, new: &Screen) -> Option<Scroll> {
+        if (self.width, self.height) != (new.width, new.height) {
+            return None;
+        }
+        let hash = |screen: &Screen, y: usize| {
+            screen.row(y).iter().fold(0xCBF2_9CE4_8422_2325u64, |h, &p| (h ^ p as u64).wrapping_mul(0x100_0000_01B3))
+        };
+        let old_hashes: Vec<u64> = (0..self.height).map(|y| hash(self, y)).collect();
+        let new_hashes: Vec<u64> = (0..new.height).map(|y| hash(new, y)).collect();
+        let first = (0..new.height).find(|&y| old_hashes[y] != new_hashes[y])?;
+
+        // Of the old rows nearest it that could have moved to the first
+        // changed one, the one whose block saves sending the most rows
+        let mut candidates: Vec<usize> =
+            (0..self.height).filter(|&y| y != first && old_hashes[y] == new_hashes[first]).collect();
+        candidates.sort_unstable_by_key(|&y| y.abs_diff(first));
+        let mut best: Option<(Scroll, usize)> = None;
+        for &from in candidates.iter().take(MAX_CANDIDATES) {
+            let matching = |&i: &usize| old_hashes[from + i] == new_hashes[first + i];
+            let rows = (0..self.height - from.max(first)).take_while(matching).count();
+            let saved = (first..first + rows).filter(|&y| old_hashes[y] != new_hashes[y]).count();
+            if saved >= MIN_SCROLL_ROWS && best.map_or(true, |(_, most)| saved > most) {
+                best = Some((Scroll { from, to: first, rows }, saved));
+            }
+        }
+        // Hashes can collide; the rows themselves cannot
+        let (mut scroll, _) = best?;
+        scroll.rows = (0..scroll.rows).take_while(|&i| self.row(scroll.from + i) == new.row(scroll.to + i)).count();
+        (scroll.rows >= MIN_SCROLL_ROWS).then_some(scroll)
+    }
+
+    /// Where `new` differs from this screen within `area`, a tile at a
+    /// time, with changed tiles side by side joined
+    pub fn changes(&self, new: &Screen, area: Region) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+        for y in (area.y..area.y + area.height).step_by(TILE) {
+            let height = TILE.min(area.y + area.height - y);
+            for x in (area.x..area.x + area.width).step_by(TILE) {
+                let width = TILE.min(area.x + area.width - x);
+                let changed = (y..y + height).any(|row| {
+                    let start = row * self.width + x;
+                    self.pixels[start..start + width] != new.pixels[start..start + width]
+                });
+                if !changed {
+                    continue;
+                }
+                match regions.last_mut() {
+                    Some(last) if last.y == y && last.x + last.width == x => last.width += width,
+                    _ => regions.push(Region { x, y, width, height }),
+                }
+            }
+        }
+        regions
+    }
+}
@@ -6,6 +6,7 @@ use alloc::collections::BTreeMap;
 use spin::Mutex;
 use lazy_static::lazy_static;
 use core::ffi::CStr;
+use crate::nt::security::{Acl, SecurityDescriptor, SecurityDescriptorControl, Sid};
 
 // Define HKEY type locally if not defined elsewhere
 type HKEY = usize;
@@ -418,4 +419,189 @@ pub extern "C" fn RegEnumValueA(
     
     // For now, return no more items
     259 // ERROR_NO_MORE_ITEMS
-}
\ No newline at end of file
+}
+// File security
+
+const ERROR_INVALID_PARAMETER: DWORD = 87;
+const ERROR_INSUFFICIENT_BUFFER: DWORD = 122;
+const ERROR_INVALID_SECURITY_DESCR: DWORD = 1338;
+
+fn fail(error: DWORD) -> BOOL {
+    super::kernel32::SetLastError(error);
+    0
+}
+
+pub(super) fn file_error(error: crate::fs::FileSystemError) -> DWORD {
+    use crate::fs::FileSystemError;
+    match error {
+        FileSystemError::NotFound | FileSystemError::FileNotFound => ERROR_FILE_NOT_FOUND,
+        FileSystemError::PermissionDenied => ERROR_ACCESS_DENIED,
+        FileSystemError::Locked => ERROR_LOCK_VIOLATION,
+        _ => ERROR_INVALID_PARAMETER,
+    }
+}
+
+/// A NUL-terminated UTF-16 string as UTF-8; a lone surrogate, which
+/// Windows allows in names, reads as U+FFFD
+pub(super) unsafe fn wide_to_string(ptr: LPCWSTR) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    Some(crate::fs::unicode::decode_utf16(core::slice::from_raw_parts(ptr, len).iter().copied()))
+}
+
+/// Read a SECURITY_DESCRIPTOR passed by an application, in either
+/// self-relative or absolute (pointer-based) form
+unsafe fn read_security_descriptor(ptr: *const u8) -> Result<SecurityDescriptor, DWORD> {
+    if ptr.is_null() {
+        return Err(ERROR_INVALID_SECURITY_DESCR);
+    }
+    let header = core::slice::from_raw_parts(ptr, 20);
+    let control = SecurityDescriptorControl::from_bits_truncate(u16::from_le_bytes([header[2], header[3]]));
+
+    let sid_length = |sid: *const u8| 8 + 4 * *sid.add(1) as usize;
+    let acl_length = |acl: *const u8| u16::from_le_bytes([*acl.add(2), *acl.add(3)]) as usize;
+
+    if control.contains(SecurityDescriptorControl::SE_SELF_RELATIVE) {
+        // Work out the extent of the descriptor from its parts
+        let offset = |field: usize| u32::from_le_bytes([header[field], header[field + 1], header[field + 2], header[field + 3]]) as usize;
+        let mut length = 20;
+        for (field, is_sid) in [(4, true), (8, true), (12, false), (16, false)] {
+            let start = offset(field);
+            if start != 0 {
+                let part = ptr.add(start);
+                length = length.max(start + if is_sid { sid_length(part) } else { acl_length(part) });
+            }
+        }
+        return SecurityDescriptor::from_self_relative(core::slice::from_raw_parts(ptr, length))
+            .map_err(|_| ERROR_INVALID_SECURITY_DESCR);
+    }
+
+    // Absolute form: revision, sbz1, control, then Owner, Group, Sacl and
+    // Dacl pointers
+    let pointers = ptr.add(8) as *const *const u8;
+    let (owner, group, sacl, dacl) = (*pointers, *pointers.add(1), *pointers.add(2), *pointers.add(3));
+    let mut sd = SecurityDescriptor::new();
+    sd.control = control;
+    if !owner.is_null() {
+        sd.owner_sid = Some(Sid::from_bytes(core::slice::from_raw_parts(owner, sid_length(owner)))
+            .map_err(|_| ERROR_INVALID_SECURITY_DESCR)?);
+    }
+    if !group.is_null() {
+        sd.group_sid = Some(Sid::from_bytes(core::slice::from_raw_parts(group, sid_length(group)))
+            .map_err(|_| ERROR_INVALID_SECURITY_DESCR)?);
+    }
+    if control.contains(SecurityDescriptorControl::SE_SACL_PRESENT) && !sacl.is_null() {
+        sd.sacl = Some(Acl::from_bytes(core::slice::from_raw_parts(sacl, acl_length(sacl)))
+            .map_err(|_| ERROR_INVALID_SECURITY_DESCR)?);
+    }
+    if control.contains(SecurityDescriptorControl::SE_DACL_PRESENT) && !dacl.is_null() {
+        sd.dacl = Some(Acl::from_bytes(core::slice::from_raw_parts(dacl, acl_length(dacl)))
+            .map_err(|_| ERROR_INVALID_SECURITY_DESCR)?);
+    }
+    Ok(sd)
+}
+
+fn get_file_security(
+    path: &str,
+    requested_information: DWORD,
+    security_descriptor: *mut u8,
+    length: DWORD,
+    length_needed: *mut DWORD,
+) -> BOOL {
+    if length_needed.is_null() {
+        return fail(ERROR_INVALID_PARAMETER);
+    }
+    let sd = match crate::fs::vfs::VFS.lock().get_file_security(path, requested_information) {
+        Ok(sd) => sd.to_self_relative(),
+        Err(error) => return fail(file_error(error)),
+    };
+
+    unsafe {
+        *length_needed = sd.len() as DWORD;
+        if security_descriptor.is_null() || (length as usize) < sd.len() {
+            return fail(ERROR_INSUFFICIENT_BUFFER);
+        }
+        core::ptr::copy_nonoverlapping(sd.as_ptr(), security_descriptor, sd.len());
+    }
+    1
+}
+
+fn set_file_security(path: &str, security_information: DWORD, security_descriptor: *const u8) -> BOOL {
+    let sd = match unsafe { read_security_descriptor(security_descriptor) } {
+        Ok(sd) => sd,
+        Err(error) => return fail(error),
+    };
+    match crate::fs::vfs::VFS.lock().set_file_security(path, security_information, &sd) {
+        Ok(()) => 1,
+        Err(error) => fail(file_error(error)),
+    }
+}
+
+/// GetFileSecurityA - Get the security descriptor of a file or directory
+#[no_mangle]
+pub extern "C" fn GetFileSecurityA(
+    file_name: LPCSTR,
+    requested_information: DWORD,
+    security_descriptor: *mut u8,
+    length: DWORD,
+    length_needed: *mut DWORD,
+) -> BOOL {
+    if file_name.is_null() {
+        return fail(ERROR_INVALID_PARAMETER);
+    }
+    let path = match unsafe { CStr::from_ptr(file_name as *const i8) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return fail(ERROR_INVALID_PARAMETER),
+    };
+    get_file_security(path, requested_information, security_descriptor, length, length_needed)
+}
+
+/// GetFileSecurityW - Get the security descriptor of a file or directory
+#[no_mangle]
+pub extern "C" fn GetFileSecurityW(
+    file_name: LPCWSTR,
+    requested_information: DWORD,
+    security_descriptor: *mut u8,
+    length: DWORD,
+    length_needed: *mut DWORD,
+) -> BOOL {
+    match unsafe { wide_to_string(file_name) } {
+        Some(path) => get_file_security(&path, requested_information, security_descriptor, length, length_needed),
+        None => fail(ERROR_INVALID_PARAMETER),
+    }
+}
+
+/// SetFileSecurityA - Set the security descriptor of a file or directory
+#[no_mangle]
+pub extern "C" fn SetFileSecurityA(
+    file_name: LPCSTR,
+    security_information: DWORD,
+    security_descriptor: *const u8,
+) -> BOOL {
+    if file_name.is_null() {
+        return fail(ERROR_INVALID_PARAMETER);
+    }
+    let path = match unsafe { CStr::from_ptr(file_name as *const i8) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return fail(ERROR_INVALID_PARAMETER),
+    };
+    set_file_security(path, security_information, security_descriptor)
+}
+
+/// SetFileSecurityW - Set the security descriptor of a file or directory
+#[no_mangle]
+pub extern "C" fn SetFileSecurityW(
+    file_name: LPCWSTR,
+    security_information: DWORD,
+    security_descriptor: *const u8,
+) -> BOOL {
+    match unsafe { wide_to_string(file_name) } {
+        Some(path) => set_file_security(&path, security_information, security_descriptor),
+        None => fail(ERROR_INVALID_PARAMETER),
+    }
+}
@@ -0,0 +1,458 @@
+//! Video for Windows capture
+//!
+//! avicap32.dll's capture window, through which apps reached a camera
+//! before DirectShow. capCreateCaptureWindow makes a window of class
+//! ClsCapWin, and the capXxx macros are WM_CAP_ messages sent to it:
+//! connecting to driver N opens the Nth camera in the capture manager and
+//! starts it streaming, and a frame grab waits for the camera's next frame
+//! and hands it to the frame callback in a VIDEOHDR. Formats are
+//! BITMAPINFOHEADERs with biCompression YUY2, NV12 or MJPG, as the camera
+//! sends them; nothing is converted.
+//!
+//! Capture windows have no pixels, so there is no preview or overlay, and
+//! there is no streaming to AVI files or to the video stream callback:
+//! those messages return FALSE.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::mem::size_of;
+
+use spin::{Mutex, RwLock};
+
+use super::message::{DefWindowProcA, WM_USER};
+use super::window::{WindowClass, WINDOW_MANAGER, WM_CREATE, WM_DESTROY};
+use super::{Handle, BOOL, DWORD, HANDLE, LPCSTR, LPCWSTR, LPSTR, LPWSTR};
+use crate::multimedia::capture::{CaptureDevice, DeviceType, CAPTURE_MANAGER};
+use crate::multimedia::{MediaFormat, MediaType, PixelFormat};
+
+pub const WM_CAP_START: u32 = WM_USER;
+pub const WM_CAP_UNICODE_START: u32 = WM_USER + 100;
+pub const WM_CAP_SET_CALLBACK_FRAME: u32 = WM_CAP_START + 5;
+pub const WM_CAP_GET_USER_DATA: u32 = WM_CAP_START + 8;
+pub const WM_CAP_SET_USER_DATA: u32 = WM_CAP_START + 9;
+pub const WM_CAP_DRIVER_CONNECT: u32 = WM_CAP_START + 10;
+pub const WM_CAP_DRIVER_DISCONNECT: u32 = WM_CAP_START + 11;
+pub const WM_CAP_DRIVER_GET_NAMEA: u32 = WM_CAP_START + 12;
+pub const WM_CAP_DRIVER_GET_NAMEW: u32 = WM_CAP_UNICODE_START + 12;
+pub const WM_CAP_DRIVER_GET_CAPS: u32 = WM_CAP_START + 14;
+pub const WM_CAP_GET_VIDEOFORMAT: u32 = WM_CAP_START + 44;
+pub const WM_CAP_SET_VIDEOFORMAT: u32 = WM_CAP_START + 45;
+pub const WM_CAP_GRAB_FRAME: u32 = WM_CAP_START + 60;
+pub const WM_CAP_GRAB_FRAME_NOSTOP: u32 = WM_CAP_START + 61;
+
+pub const VHDR_DONE: DWORD = 0x0000_0001;
+pub const VHDR_KEYFRAME: DWORD = 0x0000_0008;
+
+const CLASS_NAME: &str = "ClsCapWin";
+// How long a frame grab waits for the camera
+const GRAB_TIMEOUT_MS: u64 = 2000;
+
+// biCompression values
+const FOURCC_YUY2: DWORD = u32::from_le_bytes(*b"YUY2");
+const FOURCC_NV12: DWORD = u32::from_le_bytes(*b"NV12");
+const FOURCC_MJPG: DWORD = u32::from_le_bytes(*b"MJPG");
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BitmapInfoHeader {
+    pub size: DWORD,
+    pub width: i32,
+    /// Positive for bottom-up rows; camera formats are given top-down
+    pub height: i32,
+    pub planes: u16,
+    pub bit_count: u16,
+    pub compression: DWORD,
+    pub size_image: DWORD,
+    pub x_pels_per_meter: i32,
+    pub y_pels_per_meter: i32,
+    pub clr_used: DWORD,
+    pub clr_important: DWORD,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VideoHdr {
+    pub data: *mut u8,
+    pub buffer_length: DWORD,
+    pub bytes_used: DWORD,
+    /// Milliseconds since the driver was connected
+    pub time_captured: DWORD,
+    pub user: usize,
+    pub flags: DWORD,
+    pub reserved: [usize; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CapDriverCaps {
+    pub device_index: u32,
+    pub has_overlay: BOOL,
+    pub has_dlg_video_source: BOOL,
+    pub has_dlg_video_format: BOOL,
+    pub has_dlg_video_display: BOOL,
+    pub capture_initialized: BOOL,
+    pub driver_supplies_palettes: BOOL,
+    pub video_in: u64,
+    pub video_out: u64,
+    pub video_ext_in: u64,
+    pub video_ext_out: u64,
+}
+
+/// capSetCallbackOnFrame's callback
+pub type FrameCallback = extern "C" fn(HANDLE, *mut VideoHdr) -> isize;
+
+struct Driver {
+    index: u32,
+    device: Arc<RwLock<dyn CaptureDevice>>,
+    connected_ns: u64,
+}
+
+#[derive(Default)]
+struct CaptureWindow {
+    driver: Option<Driver>,
+    frame_callback: Option<FrameCallback>,
+    user_data: isize,
+    /// The last frame grabbed, which the VIDEOHDR points into
+    frame: Vec<u8>,
+}
+
+// Capture windows by handle
+static WINDOWS: Mutex<BTreeMap<u64, CaptureWindow>> = Mutex::new(BTreeMap::new());
+
+// The cameras, in the order their driver indexes number them
+fn cameras() -> Vec<String> {
+    CAPTURE_MANAGER
+        .enumerate_devices()
+        .into_iter()
+        .filter(|device| device.device_type == DeviceType::Camera)
+        .map(|device| device.name)
+        .collect()
+}
+
+// Copy a string to a caller's buffer of `size` bytes, truncated and
+// terminated
+fn copy_str(text: &str, out: LPSTR, size: usize) -> bool {
+    if out.is_null() || size == 0 {
+        return false;
+    }
+    let length = text.len().min(size - 1);
+    unsafe {
+        core::ptr::copy_nonoverlapping(text.as_ptr(), out, length);
+        *out.add(length) = 0;
+    }
+    true
+}
+
+fn copy_wide(text: &str, out: LPWSTR, size: usize) -> bool {
+    if out.is_null() || size == 0 {
+        return false;
+    }
+    let wide: Vec<u16> = text.encode_utf16().take(size - 1).chain([0]).collect();
+    unsafe { core::ptr::copy_nonoverlapping(wide.as_ptr(), out, wide.len()) };
+    true
+}
+
+fn header(format: &MediaFormat) -> Option<BitmapInfoHeader> {
+    let (compression, bit_count) = match format.pixel_format? {
+        PixelFormat::YUYV => (FOURCC_YUY2, 16),
+        PixelFormat::NV12 => (FOURCC_NV12, 12),
+        PixelFormat::MJPEG => (FOURCC_MJPG, 24),
+        _ => return None,
+    };
+    let (width, height) = (format.width?, format.height?);
+    Some(BitmapInfoHeader {
+        size: size_of::<BitmapInfoHeader>() as DWORD,
+        width: width as i32,
+        height: -(height as i32),
+        planes: 1,
+        bit_count,
+        compression,
+        size_image: width * height * bit_count as u32 / 8,
+        ..Default::default()
+    })
+}
+
+fn format(header: &BitmapInfoHeader) -> Option<MediaFormat> {
+    let pixel_format = match header.compression {
+        FOURCC_YUY2 => PixelFormat::YUYV,
+        FOURCC_NV12 => PixelFormat::NV12,
+        FOURCC_MJPG => PixelFormat::MJPEG,
+        _ => return None,
+    };
+    Some(MediaFormat {
+        media_type: MediaType::Video,
+        codec: String::from(if pixel_format == PixelFormat::MJPEG { "mjpeg" } else { "raw" }),
+        bitrate: None,
+        sample_rate: None,
+        channels: None,
+        width: Some(header.width.unsigned_abs()),
+        height: Some(header.height.unsigned_abs()),
+        framerate: None,
+        pixel_format: Some(pixel_format),
+        audio_format: None,
+        extra_data: Vec::new(),
+    })
+}
+
+// The connected camera of a capture window
+fn device(hwnd: HANDLE) -> Option<Arc<RwLock<dyn CaptureDevice>>> {
+    WINDOWS.lock().get(&hwnd.0)?.driver.as_ref().map(|driver| driver.device.clone())
+}
+
+fn connect(hwnd: HANDLE, index: usize) -> bool {
+    disconnect(hwnd);
+    let Some(name) = cameras().into_iter().nth(index) else {
+        return false;
+    };
+    let Some(device) = CAPTURE_MANAGER.get_device(&name) else {
+        return false;
+    };
+    {
+        let mut camera = device.write();
+        if camera.open().is_err() {
+            return false;
+        }
+        if camera.start_capture().is_err() {
+            let _ = camera.close();
+            return false;
+        }
+    }
+    let driver = Driver { index: index as u32, device, connected_ns: crate::time::clocksource::now_ns() };
+    match WINDOWS.lock().get_mut(&hwnd.0) {
+        Some(window) => window.driver = Some(driver),
+        None => {
+            let _ = driver.device.write().close();
+            return false;
+        }
+    }
+    true
+}
+
+fn disconnect(hwnd: HANDLE) -> bool {
+    let driver = WINDOWS.lock().get_mut(&hwnd.0).and_then(|window| window.driver.take());
+    match driver {
+        Some(driver) => driver.device.write().close().is_ok(),
+        None => false,
+    }
+}
+
+// Restart the camera in a new format
+fn set_format(hwnd: HANDLE, header: *const BitmapInfoHeader) -> bool {
+    if header.is_null() {
+        return false;
+    }
+    let (Some(device), Some(format)) = (device(hwnd), format(unsafe { &*header })) else {
+        return false;
+    };
+    let mut camera = device.write();
+    let _ = camera.stop_capture();
+    let changed = camera.set_format(&format).is_ok();
+    camera.start_capture().is_ok() && changed
+}
+
+// Wait for the next frame and pass it to the frame callback
+fn grab(hwnd: HANDLE) -> bool {
+    let Some(device) = device(hwnd) else {
+        return false;
+    };
+    let Ok(frame) = crate::usb::uvc::next_frame(&device, GRAB_TIMEOUT_MS) else {
+        return false;
+    };
+    let (callback, mut header) = {
+        let mut windows = WINDOWS.lock();
+        let Some(window) = windows.get_mut(&hwnd.0) else {
+            return false;
+        };
+        let connected_ns = window.driver.as_ref().map_or(frame.timestamp, |driver| driver.connected_ns);
+        window.frame = frame.data;
+        let header = VideoHdr {
+            data: window.frame.as_mut_ptr(),
+            buffer_length: window.frame.len() as DWORD,
+            bytes_used: window.frame.len() as DWORD,
+            time_captured: (frame.timestamp.saturating_sub(connected_ns) / 1_000_000) as DWORD,
+            user: 0,
+            flags: VHDR_DONE | VHDR_KEYFRAME,
+            reserved: [0; 4],
+        };
+        (window.frame_callback, header)
+    };
+    // Called with no lock held, as the app may send the window messages
+    if let Some(callback) = callback {
+        callback(hwnd, &mut header);
+    }
+    true
+}
+
+extern "C" fn capture_window_proc(hwnd: HANDLE, msg: u32, wparam: usize, lparam: isize) -> isize {
+    match msg {
+        // Sent under the window manager's lock; the state is added after
+        WM_CREATE => 0,
+        WM_DESTROY => {
+            disconnect(hwnd);
+            WINDOWS.lock().remove(&hwnd.0);
+            0
+        }
+        WM_CAP_SET_CALLBACK_FRAME => {
+            let callback = (lparam != 0).then(|| unsafe { core::mem::transmute::<isize, FrameCallback>(lparam) });
+            match WINDOWS.lock().get_mut(&hwnd.0) {
+                Some(window) => {
+                    window.frame_callback = callback;
+                    1
+                }
+                None => 0,
+            }
+        }
+        WM_CAP_GET_USER_DATA => WINDOWS.lock().get(&hwnd.0).map_or(0, |window| window.user_data),
+        WM_CAP_SET_USER_DATA => match WINDOWS.lock().get_mut(&hwnd.0) {
+            Some(window) => {
+                window.user_data = lparam;
+                1
+            }
+            None => 0,
+        },
+        WM_CAP_DRIVER_CONNECT => connect(hwnd, wparam) as isize,
+        WM_CAP_DRIVER_DISCONNECT => disconnect(hwnd) as isize,
+        WM_CAP_DRIVER_GET_NAMEA | WM_CAP_DRIVER_GET_NAMEW => {
+            let Some(device) = device(hwnd) else {
+                return 0;
+            };
+            let name = String::from(device.read().get_name());
+            let copied = match msg {
+                WM_CAP_DRIVER_GET_NAMEA => copy_str(&name, lparam as LPSTR, wparam),
+                _ => copy_wide(&name, lparam as LPWSTR, wparam),
+            };
+            copied as isize
+        }
+        WM_CAP_DRIVER_GET_CAPS => {
+            let caps = lparam as *mut CapDriverCaps;
+            if caps.is_null() || wparam < size_of::<CapDriverCaps>() {
+                return 0;
+            }
+            let Some(index) = WINDOWS.lock().get(&hwnd.0).and_then(|w| w.driver.as_ref().map(|d| d.index)) else {
+                return 0;
+            };
+            let out = CapDriverCaps { device_index: index, capture_initialized: 1, ..Default::default() };
+            unsafe { *caps = out };
+            1
+        }
+        // With no buffer, the size a format takes
+        WM_CAP_GET_VIDEOFORMAT => {
+            let Some(header) = device(hwnd).and_then(|device| header(&device.read().get_format())) else {
+                return 0;
+            };
+            let out = lparam as *mut BitmapInfoHeader;
+            if !out.is_null() && wparam >= size_of::<BitmapInfoHeader>() {
+                unsafe { *out = header };
+            }
+            size_of::<BitmapInfoHeader>() as isize
+        }
+        WM_CAP_SET_VIDEOFORMAT => {
+            if wparam < size_of::<BitmapInfoHeader>() {
+                return 0;
+            }
+            set_format(hwnd, lparam as *const BitmapInfoHeader) as isize
+        }
+        WM_CAP_GRAB_FRAME | WM_CAP_GRAB_FRAME_NOSTOP => grab(hwnd) as isize,
+        // Preview, overlay, AVI capture and the rest need pixels or files
+        // this window does not have
+        _ if (WM_CAP_START..WM_CAP_UNICODE_START + 100).contains(&msg) => 0,
+        _ => DefWindowProcA(hwnd, msg, wparam, lparam),
+    }
+}
+
+fn create(name: &str, style: DWORD, x: i32, y: i32, width: i32, height: i32, parent: HANDLE) -> HANDLE {
+    let mut manager = WINDOW_MANAGER.lock();
+    manager.register_class(WindowClass {
+        name: String::from(CLASS_NAME),
+        style: 0,
+        wnd_proc: capture_window_proc,
+        class_extra: 0,
+        window_extra: 0,
+        instance: None,
+        icon: None,
+        cursor: None,
+        background: None,
+        menu_name: None,
+    });
+    let parent = (parent != Handle::NULL).then_some(parent);
+    let Some(hwnd) = manager.create_window(CLASS_NAME, name, style, 0, x, y, width, height, parent, None, None) else {
+        return Handle::NULL;
+    };
+    drop(manager);
+    WINDOWS.lock().insert(hwnd.0, CaptureWindow::default());
+    hwnd
+}
+
+/// capCreateCaptureWindowA - Create a capture window
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn capCreateCaptureWindowA(
+    window_name: LPCSTR,
+    style: DWORD,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    parent: HANDLE,
+    _id: i32,
+) -> HANDLE {
+    let name = if window_name.is_null() {
+        ""
+    } else {
+        unsafe { CStr::from_ptr(window_name as *const i8) }.to_str().unwrap_or("")
+    };
+    create(name, style, x, y, width, height, parent)
+}
+
+/// capCreateCaptureWindowW - Create a capture window (wide version)
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn capCreateCaptureWindowW(
+    window_name: LPCWSTR,
+    style: DWORD,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    parent: HANDLE,
+    _id: i32,
+) -> HANDLE {
+    let name = unsafe { super::advapi32::wide_to_string(window_name) }.unwrap_or_default();
+    create(&name, style, x, y, width, height, parent)
+}
+
+/// capGetDriverDescriptionA - Name the Nth camera; the version is left
+/// empty
+#[no_mangle]
+pub extern "C" fn capGetDriverDescriptionA(
+    index: u16,
+    name: LPSTR,
+    name_size: i32,
+    version: LPSTR,
+    version_size: i32,
+) -> BOOL {
+    let Some(camera) = cameras().into_iter().nth(index as usize) else {
+        return 0;
+    };
+    copy_str("", version, version_size.max(0) as usize);
+    copy_str(&camera, name, name_size.max(0) as usize) as BOOL
+}
+
+/// capGetDriverDescriptionW - Name the Nth camera (wide version)
+#[no_mangle]
+pub extern "C" fn capGetDriverDescriptionW(
+    index: u16,
+    name: LPWSTR,
+    name_size: i32,
+    version: LPWSTR,
+    version_size: i32,
+) -> BOOL {
+    let Some(camera) = cameras().into_iter().nth(index as usize) else {
+        return 0;
+    };
+    copy_wide("", version, version_size.max(0) as usize);
+    copy_wide(&camera, name, name_size.max(0) as usize) as BOOL
+}
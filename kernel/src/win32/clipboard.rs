@@ -0,0 +1,367 @@
+// USER32 clipboard functions, over the system clipboard
+//
+// A program opens the clipboard, empties it to become its owner, sets data
+// as global memory blocks (or a bitmap for CF_BITMAP), and closes it. What
+// it sets goes onto the system clipboard as one change when it closes, so
+// readers never see half of it and listeners hear of it once. Blocks given
+// to SetClipboardData, and those GetClipboardData hands out, belong to the
+// clipboard until its contents change.
+//
+// Bitmaps are kept as CF_DIB; a CF_BITMAP is made from the DIB when asked
+// for, and a bitmap set is turned into one.
+
+use super::kernel32::{global_bytes, global_from, GlobalFree};
+use super::message::post_message;
+use super::window::get_current_thread_id;
+use super::*;
+use crate::clipboard::{self, Image, CF_BITMAP, CF_DIB};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use spin::Mutex;
+
+pub const WM_CLIPBOARDUPDATE: u32 = 0x031D;
+
+const ERROR_INVALID_PARAMETER: DWORD = 87;
+const ERROR_INSUFFICIENT_BUFFER: DWORD = 122;
+const ERROR_CLIPBOARD_NOT_OPEN: DWORD = 1418;
+
+// The clipboard as one program has it open
+struct Session {
+    window: HANDLE,
+    thread: DWORD,
+    emptied: bool,
+    // What it has set, to be put on the clipboard when it closes
+    pending: Vec<(u32, Vec<u8>)>,
+    given: Vec<(u32, HANDLE)>,
+}
+
+struct State {
+    open: Option<Session>,
+    owner: HANDLE,
+    // Handles belonging to the clipboard, and the contents they are of
+    handles: Vec<(u32, HANDLE)>,
+    handles_sequence: u32,
+    listeners: Vec<HANDLE>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    open: None,
+    owner: Handle::NULL,
+    handles: Vec::new(),
+    handles_sequence: 0,
+    listeners: Vec::new(),
+});
+
+fn fail<T>(error: DWORD, value: T) -> T {
+    super::kernel32::SetLastError(error);
+    value
+}
+
+impl State {
+    // The open session, if the calling thread has it
+    fn session(&mut self) -> Option<&mut Session> {
+        let thread = get_current_thread_id();
+        self.open.as_mut().filter(|session| session.thread == thread)
+    }
+
+    // Free the handles of contents that have since changed
+    fn release_stale(&mut self) {
+        let sequence = clipboard::sequence();
+        if self.handles_sequence != sequence {
+            for (format, handle) in self.handles.drain(..) {
+                free(format, handle);
+            }
+            self.handles_sequence = sequence;
+        }
+    }
+}
+
+fn free(format: u32, handle: HANDLE) {
+    if format == CF_BITMAP {
+        super::gdi::GDI_MANAGER.lock().delete_object(handle);
+    } else {
+        GlobalFree(handle);
+    }
+}
+
+// A bitmap as the clipboard keeps it
+fn bitmap_to_dib(handle: HANDLE) -> Option<Vec<u8>> {
+    let gdi = super::gdi::GDI_MANAGER.lock();
+    let bitmap = gdi.bitmap(handle)?;
+    let (width, height) = (bitmap.width as usize, bitmap.height as usize);
+    let bits = bitmap.bits_per_pixel as usize;
+    let stride = (width * bits).div_ceil(16) * 2;
+    if bitmap.data.len() < stride * height {
+        return None;
+    }
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in bitmap.data.chunks(stride).take(height) {
+        for x in 0..width {
+            let pixel = match bits {
+                32 => u32::from_le_bytes([row[x * 4], row[x * 4 + 1], row[x * 4 + 2], 0]),
+                24 => u32::from_le_bytes([row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 0]),
+                // Monochrome: set bits are white
+                1 if row[x / 8] & (0x80 >> (x % 8)) != 0 => 0x00FF_FFFF,
+                1 => 0,
+                _ => return None,
+            };
+            pixels.push(pixel | 0xFF00_0000);
+        }
+    }
+    Some(clipboard::encode_dib(&Image { width, height, pixels }))
+}
+
+// A 32-bit bitmap of the clipboard's DIB
+fn dib_to_bitmap(dib: &[u8]) -> Option<HANDLE> {
+    let image = clipboard::decode_dib(dib)?;
+    let data = image.pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+    let handle = super::gdi::GDI_MANAGER.lock().create_bitmap(image.width as i32, image.height as i32, 32, data);
+    Some(handle)
+}
+
+// Let the listening windows know the clipboard changed
+fn notify(_sequence: u32) {
+    let listeners = STATE.lock().listeners.clone();
+    let mut gone = Vec::new();
+    for hwnd in listeners {
+        if !post_message(hwnd, WM_CLIPBOARDUPDATE, 0, 0) {
+            gone.push(hwnd);
+        }
+    }
+    if !gone.is_empty() {
+        STATE.lock().listeners.retain(|hwnd| !gone.contains(hwnd));
+    }
+}
+
+/// OpenClipboard - Open the clipboard for the calling thread, associated
+/// with a window or, if NULL, with no window
+#[no_mangle]
+pub extern "C" fn OpenClipboard(hwnd: HANDLE) -> BOOL {
+    let mut state = STATE.lock();
+    let thread = get_current_thread_id();
+    match &state.open {
+        Some(session) if session.thread == thread && session.window == hwnd => 1,
+        Some(_) => fail(ERROR_ACCESS_DENIED, 0),
+        None => {
+            state.open = Some(Session { window: hwnd, thread, emptied: false, pending: Vec::new(), given: Vec::new() });
+            1
+        }
+    }
+}
+
+/// CloseClipboard - Close the clipboard, putting on it what was set
+#[no_mangle]
+pub extern "C" fn CloseClipboard() -> BOOL {
+    let mut state = STATE.lock();
+    let thread = get_current_thread_id();
+    let Some(session) = state.open.take_if(|session| session.thread == thread) else {
+        return fail(ERROR_CLIPBOARD_NOT_OPEN, 0);
+    };
+    if session.emptied {
+        state.owner = session.window;
+    }
+    drop(state);
+
+    if session.emptied {
+        clipboard::replace(session.pending);
+    } else if !session.pending.is_empty() {
+        clipboard::extend(session.pending);
+    } else {
+        return 1;
+    }
+
+    // The blocks set are the clipboard's now, until it next changes
+    let mut state = STATE.lock();
+    state.release_stale();
+    state.handles.extend(session.given);
+    1
+}
+
+/// EmptyClipboard - Empty the clipboard and make the opener its owner
+#[no_mangle]
+pub extern "C" fn EmptyClipboard() -> BOOL {
+    let mut state = STATE.lock();
+    let Some(session) = state.session() else {
+        return fail(ERROR_CLIPBOARD_NOT_OPEN, 0);
+    };
+    session.emptied = true;
+    session.pending.clear();
+    for (format, handle) in session.given.drain(..) {
+        free(format, handle);
+    }
+    1
+}
+
+/// SetClipboardData - Set a format's data, as a global memory block or,
+/// for CF_BITMAP, a bitmap. Rendering data only when asked for, with a
+/// NULL handle, is not supported.
+#[no_mangle]
+pub extern "C" fn SetClipboardData(format: u32, memory: HANDLE) -> HANDLE {
+    if format == 0 || memory == Handle::NULL {
+        return fail(ERROR_INVALID_PARAMETER, Handle::NULL);
+    }
+    let given = format;
+    let (format, data) = if format == CF_BITMAP {
+        (CF_DIB, bitmap_to_dib(memory))
+    } else {
+        (format, global_bytes(memory))
+    };
+    let Some(data) = data else {
+        return fail(ERROR_INVALID_HANDLE, Handle::NULL);
+    };
+    let mut state = STATE.lock();
+    let Some(session) = state.session() else {
+        return fail(ERROR_CLIPBOARD_NOT_OPEN, Handle::NULL);
+    };
+    session.pending.retain(|(pending, _)| *pending != format);
+    session.pending.push((format, data));
+    session.given.push((given, memory));
+    memory
+}
+
+/// GetClipboardData - A format's data as a block, or a bitmap for
+/// CF_BITMAP, belonging to the clipboard
+#[no_mangle]
+pub extern "C" fn GetClipboardData(format: u32) -> HANDLE {
+    let mut state = STATE.lock();
+    let Some(session) = state.session() else {
+        return fail(ERROR_CLIPBOARD_NOT_OPEN, Handle::NULL);
+    };
+    // What this program set and has not yet closed on, or what is there
+    let wanted = if format == CF_BITMAP { CF_DIB } else { format };
+    let data = match session.pending.iter().find(|(pending, _)| *pending == wanted) {
+        Some((_, data)) => Some(data.clone()),
+        None if session.emptied => None,
+        None => clipboard::get(wanted),
+    };
+    let Some(data) = data else {
+        return Handle::NULL;
+    };
+    state.release_stale();
+    if let Some(&(_, handle)) = state.handles.iter().find(|(kept, _)| *kept == format) {
+        return handle;
+    }
+    let handle = if format == CF_BITMAP {
+        match dib_to_bitmap(&data) {
+            Some(handle) => handle,
+            None => return Handle::NULL,
+        }
+    } else {
+        global_from(&data)
+    };
+    state.handles.push((format, handle));
+    handle
+}
+
+// The formats on the clipboard, with CF_BITMAP when there is a DIB
+fn formats() -> Vec<u32> {
+    let mut formats = clipboard::formats();
+    if formats.contains(&CF_DIB) && !formats.contains(&CF_BITMAP) {
+        formats.push(CF_BITMAP);
+    }
+    formats
+}
+
+/// IsClipboardFormatAvailable - Whether the clipboard has data in a format
+#[no_mangle]
+pub extern "C" fn IsClipboardFormatAvailable(format: u32) -> BOOL {
+    formats().contains(&format) as BOOL
+}
+
+/// CountClipboardFormats - How many formats the clipboard has data in
+#[no_mangle]
+pub extern "C" fn CountClipboardFormats() -> i32 {
+    formats().len() as i32
+}
+
+/// EnumClipboardFormats - The format after `format`, or the first for 0;
+/// 0 when there are no more
+#[no_mangle]
+pub extern "C" fn EnumClipboardFormats(format: u32) -> u32 {
+    if STATE.lock().session().is_none() {
+        return fail(ERROR_CLIPBOARD_NOT_OPEN, 0);
+    }
+    let formats = formats();
+    let next = match format {
+        0 => formats.first(),
+        format => formats.iter().position(|&f| f == format).and_then(|i| formats.get(i + 1)),
+    };
+    next.copied().unwrap_or_else(|| fail(ERROR_SUCCESS, 0))
+}
+
+/// GetClipboardSequenceNumber - A number that changes with the contents
+#[no_mangle]
+pub extern "C" fn GetClipboardSequenceNumber() -> DWORD {
+    clipboard::sequence()
+}
+
+/// RegisterClipboardFormatA - The number of a named format, the same for
+/// every caller
+#[no_mangle]
+pub extern "C" fn RegisterClipboardFormatA(name: LPCSTR) -> u32 {
+    if name.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    let name = unsafe { CStr::from_ptr(name as *const i8) }.to_string_lossy();
+    clipboard::register_format(&name).unwrap_or_else(|| fail(ERROR_INVALID_PARAMETER, 0))
+}
+
+/// GetClipboardFormatNameA - A registered format's name; the predefined
+/// formats have none
+#[no_mangle]
+pub extern "C" fn GetClipboardFormatNameA(format: u32, name: LPSTR, max_count: i32) -> i32 {
+    if name.is_null() || max_count <= 0 {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    let found: Option<String> = clipboard::is_registered(format).then(|| clipboard::format_name(format)).flatten();
+    let Some(found) = found else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    let bytes = found.as_bytes();
+    if bytes.len() >= max_count as usize {
+        super::kernel32::SetLastError(ERROR_INSUFFICIENT_BUFFER);
+    }
+    let length = bytes.len().min(max_count as usize - 1);
+    unsafe {
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), name, length);
+        *name.add(length) = 0;
+    }
+    length as i32
+}
+
+/// GetClipboardOwner - The window that last emptied the clipboard
+#[no_mangle]
+pub extern "C" fn GetClipboardOwner() -> HANDLE {
+    STATE.lock().owner
+}
+
+/// GetOpenClipboardWindow - The window the clipboard is open for
+#[no_mangle]
+pub extern "C" fn GetOpenClipboardWindow() -> HANDLE {
+    STATE.lock().open.as_ref().map_or(Handle::NULL, |session| session.window)
+}
+
+/// AddClipboardFormatListener - Post WM_CLIPBOARDUPDATE to a window after
+/// each change
+#[no_mangle]
+pub extern "C" fn AddClipboardFormatListener(hwnd: HANDLE) -> BOOL {
+    if super::window::WINDOW_MANAGER.lock().window_thread(hwnd).is_none() {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    clipboard::add_listener(notify);
+    let mut state = STATE.lock();
+    if !state.listeners.contains(&hwnd) {
+        state.listeners.push(hwnd);
+    }
+    1
+}
+
+/// RemoveClipboardFormatListener - Stop posting WM_CLIPBOARDUPDATE
+#[no_mangle]
+pub extern "C" fn RemoveClipboardFormatListener(hwnd: HANDLE) -> BOOL {
+    let mut state = STATE.lock();
+    let before = state.listeners.len();
+    state.listeners.retain(|&listener| listener != hwnd);
+    (state.listeners.len() < before) as BOOL
+}
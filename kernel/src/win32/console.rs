@@ -1,11 +1,21 @@
 // Console Subsystem implementation for Win32
+//
+// The console host owns one or more screen buffers per console, an input
+// record queue fed by the keyboard driver, a line editor for cooked
+// (ENABLE_LINE_INPUT) reads and a virtual terminal sequence parser for
+// output.  The active screen buffer of an attached console is rendered to the
+// text-mode framebuffer; the boot console shares the kernel writer instead so
+// its output sits alongside the kernel log.  The shell writes through the
+// host too (`write_shell`), so escape sequences and text attributes work the
+// same for it as for Win32 programs; on the shared writer only the colours
+// carry over, and cursor addressing stays in the screen buffer.
 use super::*;
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::collections::{VecDeque, BTreeMap};
+use core::mem::{offset_of, size_of};
 use spin::Mutex;
 use lazy_static::lazy_static;
-use core::fmt::Write;
 
 // Console structure
 #[derive(Debug, Clone)]
@@ -14,14 +24,16 @@ pub struct Console {
     pub input_handle: HANDLE,
     pub output_handle: HANDLE,
     pub error_handle: HANDLE,
-    pub screen_buffer: ScreenBuffer,
+    pub screen_buffers: BTreeMap<u64, ScreenBuffer>,
+    pub active_buffer: HANDLE,
     pub input_buffer: VecDeque<InputRecord>,
     pub input_mode: DWORD,
-    pub output_mode: DWORD,
+    pub line_editor: LineEditor,
     pub title: String,
-    pub cursor_info: ConsoleCursorInfo,
     pub window_info: ConsoleWindowInfo,
     pub process_list: Vec<DWORD>,
+    // Whether the active screen buffer owns the display
+    pub attached: bool,
 }
 
 // Screen buffer for console output
@@ -32,8 +44,14 @@ pub struct ScreenBuffer {
     pub cursor_x: u16,
     pub cursor_y: u16,
     pub attributes: u16,
+    // What SGR 0 and a terminal reset go back to
+    pub default_attributes: u16,
     pub buffer: Vec<CharInfo>,
     pub active: bool,
+    pub output_mode: DWORD,
+    pub cursor_info: ConsoleCursorInfo,
+    pub saved_cursor: (u16, u16),
+    pub vt: VtParser,
 }
 
 // Character information in screen buffer
@@ -118,11 +136,176 @@ pub struct FocusEventRecord {
 
 // Coordinate structure
 #[derive(Debug, Clone, Copy)]
+#[repr(C)]
 pub struct Coord {
     pub x: i16,
     pub y: i16,
 }
 
+// C layout of INPUT_RECORD (event type + largest union member). The union
+// holds DWORDs, so it starts 4-byte aligned after two bytes of padding.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct INPUT_RECORD {
+    pub event_type: u16,
+    pub _padding: u16,
+    pub event: [u8; 16],
+}
+
+const _: () = {
+    assert!(size_of::<INPUT_RECORD>() == 20);
+    assert!(offset_of!(INPUT_RECORD, event) == 4);
+};
+
+// C layout of CONSOLE_SCREEN_BUFFER_INFO
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CONSOLE_SCREEN_BUFFER_INFO {
+    pub size: Coord,
+    pub cursor_position: Coord,
+    pub attributes: u16,
+    pub window: ConsoleWindowInfo,
+    pub maximum_window_size: Coord,
+}
+
+// C layout of CONSOLE_CURSOR_INFO
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CONSOLE_CURSOR_INFO {
+    pub size: DWORD,
+    pub visible: BOOL,
+}
+
+impl InputRecord {
+    fn to_c(&self) -> INPUT_RECORD {
+        let mut event = [0u8; 16];
+        let event_type = match self {
+            InputRecord::KeyEvent(key) => {
+                event[0..4].copy_from_slice(&(key.key_down as u32).to_le_bytes());
+                event[4..6].copy_from_slice(&key.repeat_count.to_le_bytes());
+                event[6..8].copy_from_slice(&key.virtual_key_code.to_le_bytes());
+                event[8..10].copy_from_slice(&key.virtual_scan_code.to_le_bytes());
+                event[10..12].copy_from_slice(&key.unicode_char.to_le_bytes());
+                event[12..16].copy_from_slice(&key.control_key_state.to_le_bytes());
+                KEY_EVENT
+            }
+            InputRecord::MouseEvent(mouse) => {
+                event[0..2].copy_from_slice(&mouse.mouse_position.x.to_le_bytes());
+                event[2..4].copy_from_slice(&mouse.mouse_position.y.to_le_bytes());
+                event[4..8].copy_from_slice(&mouse.button_state.to_le_bytes());
+                event[8..12].copy_from_slice(&mouse.control_key_state.to_le_bytes());
+                event[12..16].copy_from_slice(&mouse.event_flags.to_le_bytes());
+                MOUSE_EVENT
+            }
+            InputRecord::WindowBufferSizeEvent(size) => {
+                event[0..2].copy_from_slice(&size.size.x.to_le_bytes());
+                event[2..4].copy_from_slice(&size.size.y.to_le_bytes());
+                WINDOW_BUFFER_SIZE_EVENT
+            }
+            InputRecord::MenuEvent(menu) => {
+                event[0..4].copy_from_slice(&menu.command_id.to_le_bytes());
+                MENU_EVENT
+            }
+            InputRecord::FocusEvent(focus) => {
+                event[0..4].copy_from_slice(&(focus.set_focus as u32).to_le_bytes());
+                FOCUS_EVENT
+            }
+        };
+        INPUT_RECORD { event_type, _padding: 0, event }
+    }
+}
+
+// Virtual terminal escape sequence parser state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VtState {
+    Ground,
+    Escape,
+    Csi,
+}
+
+#[derive(Debug, Clone)]
+pub struct VtParser {
+    pub state: VtState,
+    pub params: Vec<u16>,
+    pub current: Option<u16>,
+    pub private: bool,
+}
+
+impl VtParser {
+    pub fn new() -> Self {
+        Self {
+            state: VtState::Ground,
+            params: Vec::new(),
+            current: None,
+            private: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = VtState::Ground;
+        self.params.clear();
+        self.current = None;
+        self.private = false;
+    }
+
+    fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params.get(index) {
+            Some(&0) | None => default,
+            Some(&value) => value,
+        }
+    }
+}
+
+// Line editor used for cooked reads (ENABLE_LINE_INPUT)
+#[derive(Debug, Clone)]
+pub struct LineEditor {
+    pub line: Vec<u8>,
+    pub cursor: usize,
+    pub history: VecDeque<String>,
+    pub history_pos: Option<usize>,
+    pub insert_mode: bool,
+    // Screen position where the line being edited starts
+    pub anchor: Option<(u16, u16)>,
+    // Completed input waiting to be returned by ReadConsole
+    pub ready: VecDeque<u8>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_pos: None,
+            insert_mode: true,
+            anchor: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn replace_line(&mut self, text: &str) {
+        self.line = text.as_bytes().to_vec();
+        self.cursor = self.line.len();
+    }
+
+    fn commit(&mut self) {
+        let text = String::from_utf8_lossy(&self.line).into_owned();
+        if !text.trim().is_empty() && self.history.back() != Some(&text) {
+            if self.history.len() >= CONSOLE_HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(text);
+        }
+        self.ready.extend(self.line.iter().copied());
+        self.ready.push_back(b'\r');
+        self.ready.push_back(b'\n');
+        self.line.clear();
+        self.cursor = 0;
+        self.history_pos = None;
+        self.anchor = None;
+    }
+}
+
 // Console Manager
 pub struct ConsoleManager {
     consoles: BTreeMap<u64, Console>,
@@ -134,6 +317,578 @@ lazy_static! {
     pub static ref CONSOLE_MANAGER: Mutex<ConsoleManager> = Mutex::new(ConsoleManager::new());
 }
 
+impl ScreenBuffer {
+    pub fn new(width: u16, height: u16, output_mode: DWORD) -> Self {
+        let mut buffer = Vec::with_capacity((width as usize) * (height as usize));
+        buffer.resize((width as usize) * (height as usize), CharInfo::default());
+
+        Self {
+            width,
+            height,
+            cursor_x: 0,
+            cursor_y: 0,
+            attributes: FOREGROUND_WHITE,
+            default_attributes: FOREGROUND_WHITE,
+            buffer,
+            active: false,
+            output_mode,
+            cursor_info: ConsoleCursorInfo {
+                size: 25,
+                visible: true,
+            },
+            saved_cursor: (0, 0),
+            vt: VtParser::new(),
+        }
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        (y as usize) * (self.width as usize) + (x as usize)
+    }
+
+    fn put_char(&mut self, x: u16, y: u16, ch: u8) {
+        let index = self.index(x, y);
+        if index < self.buffer.len() {
+            self.buffer[index] = CharInfo {
+                char: ch as u16,
+                attributes: self.attributes,
+            };
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        self.cursor_y += 1;
+        if self.cursor_y >= self.height {
+            self.scroll_up();
+            self.cursor_y = self.height - 1;
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        let width = self.width as usize;
+        self.buffer.drain(0..width);
+        let blank = CharInfo { char: b' ' as u16, attributes: self.attributes };
+        self.buffer.resize((self.width as usize) * (self.height as usize), blank);
+    }
+
+    fn clear_range(&mut self, start: usize, end: usize) {
+        let blank = CharInfo { char: b' ' as u16, attributes: self.attributes };
+        let end = end.min(self.buffer.len());
+        for cell in &mut self.buffer[start.min(end)..end] {
+            *cell = blank;
+        }
+    }
+
+    /// Write raw bytes, honouring the processed output, wrap and VT modes
+    pub fn write(&mut self, data: &[u8]) -> u32 {
+        for &byte in data {
+            self.write_byte(byte);
+        }
+        data.len() as u32
+    }
+
+    /// Apply one byte of output. False if the VT parser took it as part of
+    /// an escape sequence.
+    fn write_byte(&mut self, byte: u8) -> bool {
+        let processed = self.output_mode & ENABLE_PROCESSED_OUTPUT != 0;
+        let vt = self.output_mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0;
+
+        if vt && self.vt_consume(byte) {
+            return false;
+        }
+        if processed {
+            match byte {
+                b'\n' => {
+                    self.new_line();
+                    return true;
+                }
+                b'\r' => {
+                    self.cursor_x = 0;
+                    return true;
+                }
+                b'\t' => {
+                    let tab_size = 8 - (self.cursor_x % 8);
+                    for _ in 0..tab_size {
+                        self.write_printable(b' ');
+                    }
+                    return true;
+                }
+                b'\x08' => {
+                    self.cursor_x = self.cursor_x.saturating_sub(1);
+                    return true;
+                }
+                b'\x07' => return true, // Bell
+                _ => {}
+            }
+        }
+        self.write_printable(byte);
+        true
+    }
+
+    fn write_printable(&mut self, byte: u8) {
+        if self.cursor_x >= self.width {
+            if self.output_mode & ENABLE_WRAP_AT_EOL_OUTPUT != 0 {
+                self.new_line();
+            } else {
+                self.cursor_x = self.width - 1;
+            }
+        }
+        self.put_char(self.cursor_x, self.cursor_y, byte);
+        self.cursor_x += 1;
+    }
+
+    /// Feed a byte to the VT parser. Returns true if the byte was consumed
+    /// as part of an escape sequence.
+    fn vt_consume(&mut self, byte: u8) -> bool {
+        match self.vt.state {
+            VtState::Ground => {
+                if byte == 0x1B {
+                    self.vt.state = VtState::Escape;
+                    true
+                } else {
+                    false
+                }
+            }
+            VtState::Escape => {
+                match byte {
+                    b'[' => {
+                        self.vt.state = VtState::Csi;
+                        return true;
+                    }
+                    b'7' => self.saved_cursor = (self.cursor_x, self.cursor_y),
+                    b'8' => (self.cursor_x, self.cursor_y) = self.saved_cursor,
+                    b'c' => {
+                        self.attributes = self.default_attributes;
+                        self.clear_range(0, self.buffer.len());
+                        self.cursor_x = 0;
+                        self.cursor_y = 0;
+                    }
+                    _ => {}
+                }
+                self.vt.reset();
+                true
+            }
+            VtState::Csi => {
+                match byte {
+                    b'0'..=b'9' => {
+                        let digit = (byte - b'0') as u16;
+                        let value = self.vt.current.unwrap_or(0);
+                        self.vt.current = Some(value.saturating_mul(10).saturating_add(digit));
+                    }
+                    b';' => {
+                        let value = self.vt.current.take().unwrap_or(0);
+                        self.vt.params.push(value);
+                    }
+                    b'?' => self.vt.private = true,
+                    0x40..=0x7E => {
+                        if let Some(value) = self.vt.current.take() {
+                            self.vt.params.push(value);
+                        }
+                        self.vt_dispatch(byte);
+                        self.vt.reset();
+                    }
+                    _ => self.vt.reset(),
+                }
+                true
+            }
+        }
+    }
+
+    fn vt_dispatch(&mut self, command: u8) {
+        let max_x = self.width.saturating_sub(1);
+        let max_y = self.height.saturating_sub(1);
+        let n = self.vt.param(0, 1);
+
+        if self.vt.private {
+            // DECTCEM: ESC[?25h / ESC[?25l
+            if self.vt.param(0, 0) == 25 {
+                match command {
+                    b'h' => self.cursor_info.visible = true,
+                    b'l' => self.cursor_info.visible = false,
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        match command {
+            b'A' => self.cursor_y = self.cursor_y.saturating_sub(n),
+            b'B' => self.cursor_y = self.cursor_y.saturating_add(n).min(max_y),
+            b'C' => self.cursor_x = self.cursor_x.saturating_add(n).min(max_x),
+            b'D' => self.cursor_x = self.cursor_x.saturating_sub(n),
+            b'E' => {
+                self.cursor_y = self.cursor_y.saturating_add(n).min(max_y);
+                self.cursor_x = 0;
+            }
+            b'F' => {
+                self.cursor_y = self.cursor_y.saturating_sub(n);
+                self.cursor_x = 0;
+            }
+            b'G' => self.cursor_x = (n - 1).min(max_x),
+            b'd' => self.cursor_y = (n - 1).min(max_y),
+            b'H' | b'f' => {
+                self.cursor_y = (self.vt.param(0, 1) - 1).min(max_y);
+                self.cursor_x = (self.vt.param(1, 1) - 1).min(max_x);
+            }
+            b'J' => {
+                let cursor = self.index(self.cursor_x, self.cursor_y);
+                match self.vt.param(0, 0) {
+                    0 => self.clear_range(cursor, self.buffer.len()),
+                    1 => self.clear_range(0, cursor + 1),
+                    _ => self.clear_range(0, self.buffer.len()),
+                }
+            }
+            b'K' => {
+                let line_start = self.index(0, self.cursor_y);
+                let line_end = line_start + self.width as usize;
+                let cursor = self.index(self.cursor_x, self.cursor_y);
+                match self.vt.param(0, 0) {
+                    0 => self.clear_range(cursor, line_end),
+                    1 => self.clear_range(line_start, cursor + 1),
+                    _ => self.clear_range(line_start, line_end),
+                }
+            }
+            b'S' => {
+                // Past the height every line is blank already
+                for _ in 0..n.min(self.height) {
+                    self.scroll_up();
+                }
+            }
+            b'm' => self.vt_sgr(),
+            b's' => self.saved_cursor = (self.cursor_x, self.cursor_y),
+            b'u' => (self.cursor_x, self.cursor_y) = self.saved_cursor,
+            _ => {}
+        }
+    }
+
+    // Select Graphic Rendition: map ANSI colours onto console attributes
+    fn vt_sgr(&mut self) {
+        if self.vt.params.is_empty() {
+            self.vt.params.push(0);
+        }
+        let params = self.vt.params.clone();
+        for param in params {
+            match param {
+                0 => self.attributes = self.default_attributes,
+                1 => self.attributes |= FOREGROUND_INTENSITY,
+                22 => self.attributes &= !FOREGROUND_INTENSITY,
+                7 => {
+                    let fg = self.attributes & 0x0F;
+                    let bg = (self.attributes >> 4) & 0x0F;
+                    self.attributes = (self.attributes & !0xFF) | (fg << 4) | bg;
+                }
+                30..=37 => {
+                    self.attributes = (self.attributes & !0x07) | ansi_to_console(param - 30);
+                }
+                39 => self.attributes = (self.attributes & !0x0F) | (self.default_attributes & 0x0F),
+                40..=47 => {
+                    self.attributes = (self.attributes & !0x70) | (ansi_to_console(param - 40) << 4);
+                }
+                49 => self.attributes = (self.attributes & !0xF0) | (self.default_attributes & 0xF0),
+                90..=97 => {
+                    self.attributes = (self.attributes & !0x0F)
+                        | ansi_to_console(param - 90)
+                        | FOREGROUND_INTENSITY;
+                }
+                100..=107 => {
+                    self.attributes = (self.attributes & !0xF0)
+                        | (ansi_to_console(param - 100) << 4)
+                        | BACKGROUND_INTENSITY;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Render the visible window of this buffer to the text-mode framebuffer
+    pub fn present(&self, window: &ConsoleWindowInfo) {
+        use crate::vga_buffer::{TEXT_HEIGHT, TEXT_WIDTH};
+
+        let top = window.top.max(0) as u16;
+        let left = window.left.max(0) as u16;
+        let mut cells = Vec::with_capacity(TEXT_WIDTH * TEXT_HEIGHT);
+        for row in 0..TEXT_HEIGHT as u16 {
+            for col in 0..TEXT_WIDTH as u16 {
+                let (x, y) = (left + col, top + row);
+                let cell = if x < self.width && y < self.height {
+                    self.buffer[self.index(x, y)]
+                } else {
+                    CharInfo { char: b' ' as u16, attributes: 0 }
+                };
+                let byte = if cell.char < 0x80 { cell.char as u8 } else { 0xFE };
+                cells.push((byte, cell.attributes as u8));
+            }
+        }
+        crate::vga_buffer::blit_cells(&cells, TEXT_WIDTH);
+
+        let visible = self.cursor_info.visible
+            && self.cursor_x >= left
+            && self.cursor_y >= top;
+        crate::vga_buffer::set_cursor(
+            (self.cursor_y - top.min(self.cursor_y)) as usize,
+            (self.cursor_x - left.min(self.cursor_x)) as usize,
+            visible,
+        );
+    }
+}
+
+// ANSI colour index (R=1, G=2, B=4) to console attribute bits (B=1, G=2, R=4)
+fn ansi_to_console(color: u16) -> u16 {
+    let mut attr = 0;
+    if color & 1 != 0 { attr |= FOREGROUND_RED; }
+    if color & 2 != 0 { attr |= FOREGROUND_GREEN; }
+    if color & 4 != 0 { attr |= FOREGROUND_BLUE; }
+    attr
+}
+
+impl Console {
+    fn active_buffer_mut(&mut self) -> Option<&mut ScreenBuffer> {
+        self.screen_buffers.get_mut(&self.active_buffer.0)
+    }
+
+    /// Resolve an output handle to the screen buffer it refers to
+    fn buffer_key(&self, handle: HANDLE) -> Option<u64> {
+        if handle == self.error_handle {
+            Some(self.output_handle.0)
+        } else if self.screen_buffers.contains_key(&handle.0) {
+            Some(handle.0)
+        } else {
+            None
+        }
+    }
+
+    fn present(&self) {
+        if !self.attached {
+            return;
+        }
+        if let Some(buffer) = self.screen_buffers.get(&self.active_buffer.0) {
+            buffer.present(&self.window_info);
+        }
+    }
+
+    fn push_input(&mut self, record: InputRecord) {
+        if self.input_buffer.len() >= CONSOLE_INPUT_LIMIT {
+            self.input_buffer.pop_front();
+        }
+        self.input_buffer.push_back(record);
+    }
+
+    /// Echo bytes to the active buffer (or the kernel writer when detached)
+    fn echo(&mut self, data: &[u8]) {
+        if self.attached {
+            if let Some(buffer) = self.active_buffer_mut() {
+                buffer.write(data);
+            }
+        } else {
+            for &byte in data {
+                crate::print!("{}", byte as char);
+            }
+        }
+    }
+
+    /// Redraw the line being edited and place the cursor inside it
+    fn redraw_line(&mut self, old_len: usize) {
+        let Some((x, y)) = self.line_editor.anchor else {
+            return;
+        };
+        let line = self.line_editor.line.clone();
+        let cursor = self.line_editor.cursor;
+
+        if !self.attached {
+            // The kernel writer has no cursor addressing; rewrite the tail
+            // with backspaces instead
+            let mut out: Vec<u8> = Vec::new();
+            out.extend(core::iter::repeat(b'\x08').take(old_len));
+            out.extend_from_slice(&line);
+            let clear = old_len.saturating_sub(line.len());
+            out.extend(core::iter::repeat(b' ').take(clear));
+            out.extend(core::iter::repeat(b'\x08').take(clear));
+            self.echo(&out);
+            return;
+        }
+
+        let Some(buffer) = self.active_buffer_mut() else {
+            return;
+        };
+        let width = buffer.width as usize;
+        let start = buffer.index(x, y);
+        buffer.cursor_x = x;
+        buffer.cursor_y = y;
+        buffer.write(&line);
+        let clear_from = start + line.len();
+        let clear_to = start + old_len.max(line.len());
+        buffer.clear_range(clear_from, clear_to);
+
+        let pos = start + cursor;
+        buffer.cursor_x = (pos % width) as u16;
+        buffer.cursor_y = ((pos / width) as u16).min(buffer.height - 1);
+    }
+
+    /// Apply a key event to the line editor
+    fn edit_line(&mut self, key: &KeyEventRecord) {
+        if !key.key_down {
+            return;
+        }
+        let echo = self.input_mode & ENABLE_ECHO_INPUT != 0;
+
+        if self.line_editor.anchor.is_none() {
+            self.line_editor.anchor = self
+                .screen_buffers
+                .get(&self.active_buffer.0)
+                .map(|b| (b.cursor_x, b.cursor_y))
+                .or(Some((0, 0)));
+        }
+
+        let old_len = self.line_editor.line.len();
+        let ch = key.unicode_char as u8;
+
+        match key.virtual_key_code {
+            VK_RETURN => {
+                if echo {
+                    let cursor = self.line_editor.cursor;
+                    self.line_editor.cursor = self.line_editor.line.len();
+                    if cursor != self.line_editor.cursor {
+                        self.redraw_line(old_len);
+                    }
+                    self.echo(b"\r\n");
+                }
+                self.line_editor.commit();
+                return;
+            }
+            VK_BACK => {
+                let editor = &mut self.line_editor;
+                if editor.cursor > 0 {
+                    editor.cursor -= 1;
+                    editor.line.remove(editor.cursor);
+                }
+            }
+            VK_DELETE => {
+                let editor = &mut self.line_editor;
+                if editor.cursor < editor.line.len() {
+                    editor.line.remove(editor.cursor);
+                }
+            }
+            VK_LEFT => {
+                self.line_editor.cursor = self.line_editor.cursor.saturating_sub(1);
+            }
+            VK_RIGHT => {
+                let editor = &mut self.line_editor;
+                editor.cursor = (editor.cursor + 1).min(editor.line.len());
+            }
+            VK_HOME => self.line_editor.cursor = 0,
+            VK_END => self.line_editor.cursor = self.line_editor.line.len(),
+            VK_INSERT if key.control_key_state & SHIFT_PRESSED != 0 => self.paste_line(),
+            VK_INSERT => self.line_editor.insert_mode = !self.line_editor.insert_mode,
+            VK_ESCAPE => {
+                self.line_editor.line.clear();
+                self.line_editor.cursor = 0;
+            }
+            VK_UP | VK_DOWN => {
+                let editor = &mut self.line_editor;
+                if editor.history.is_empty() {
+                    return;
+                }
+                let last = editor.history.len() - 1;
+                let pos = match (editor.history_pos, key.virtual_key_code) {
+                    (None, VK_UP) => Some(last),
+                    (None, _) => None,
+                    (Some(p), VK_UP) => Some(p.saturating_sub(1)),
+                    (Some(p), _) if p < last => Some(p + 1),
+                    (Some(_), _) => None,
+                };
+                editor.history_pos = pos;
+                let text = pos.map(|p| editor.history[p].clone()).unwrap_or_default();
+                editor.replace_line(&text);
+            }
+            // Ctrl+V
+            _ if key.unicode_char == 0x16 => self.paste_line(),
+            _ => {
+                if ch < 0x20 || ch == 0x7F || key.unicode_char > 0xFF {
+                    return;
+                }
+                let editor = &mut self.line_editor;
+                if editor.insert_mode || editor.cursor >= editor.line.len() {
+                    editor.line.insert(editor.cursor, ch);
+                } else {
+                    editor.line[editor.cursor] = ch;
+                }
+                editor.cursor += 1;
+
+                // Fast path: appending at the end only needs the character
+                if echo && editor.cursor == editor.line.len() && editor.line.len() == old_len + 1 {
+                    self.echo(&[ch]);
+                    return;
+                }
+            }
+        }
+
+        if echo {
+            self.redraw_line(old_len);
+        }
+    }
+
+    /// Type the first line of the clipboard's text at the cursor. Later
+    /// lines are left out, so a paste never ends the line being read.
+    fn paste_line(&mut self) {
+        let Some(text) = crate::clipboard::text() else {
+            return;
+        };
+        let editor = &mut self.line_editor;
+        let line = text.lines().next().unwrap_or("");
+        for byte in line.chars().filter_map(|c| u8::try_from(c).ok()).filter(|&b| b >= 0x20 && b != 0x7F) {
+            editor.line.insert(editor.cursor, byte);
+            editor.cursor += 1;
+        }
+    }
+
+    /// Read cooked input. Returns None while no complete line is available.
+    fn read_line_input(&mut self, out: &mut [u8]) -> Option<u32> {
+        while self.line_editor.ready.is_empty() {
+            let Some(record) = self.input_buffer.pop_front() else {
+                // Show the partially edited line before waiting for more keys
+                self.present();
+                return None;
+            };
+            if let InputRecord::KeyEvent(key) = record {
+                self.edit_line(&key);
+            }
+        }
+        self.present();
+
+        let mut read = 0;
+        while read < out.len() {
+            match self.line_editor.ready.pop_front() {
+                Some(byte) => {
+                    out[read] = byte;
+                    read += 1;
+                }
+                None => break,
+            }
+        }
+        Some(read as u32)
+    }
+
+    /// Read raw input: each key-down character is returned as typed
+    fn read_raw_input(&mut self, out: &mut [u8]) -> Option<u32> {
+        let mut read = 0;
+        while read < out.len() {
+            let Some(record) = self.input_buffer.pop_front() else {
+                break;
+            };
+            if let InputRecord::KeyEvent(key) = record {
+                if key.key_down && key.unicode_char != 0 && key.unicode_char < 0x100 {
+                    out[read] = key.unicode_char as u8;
+                    read += 1;
+                    if self.input_mode & ENABLE_ECHO_INPUT != 0 {
+                        self.echo(&[key.unicode_char as u8]);
+                    }
+                }
+            }
+        }
+        if read == 0 { None } else { Some(read as u32) }
+    }
+}
+
 impl ConsoleManager {
     pub fn new() -> Self {
         let mut manager = Self {
@@ -141,44 +896,42 @@ impl ConsoleManager {
             next_handle: 0x20000,
             active_console: None,
         };
-        
+
         // Create default console
         manager.create_default_console();
-        
+
         manager
     }
-    
+
     fn create_default_console(&mut self) {
         let handle = self.allocate_handle();
         let input_handle = self.allocate_handle();
         let output_handle = self.allocate_handle();
         let error_handle = self.allocate_handle();
-        
-        let mut buffer = Vec::with_capacity((80 * 25) as usize);
-        buffer.resize((80 * 25) as usize, CharInfo::default());
-        
+
+        let mut screen_buffer = ScreenBuffer::new(
+            80,
+            25,
+            ENABLE_PROCESSED_OUTPUT | ENABLE_WRAP_AT_EOL_OUTPUT | ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        );
+        screen_buffer.active = true;
+        // Until a program changes them, text keeps the kernel writer's colours
+        screen_buffer.attributes = crate::vga_buffer::attribute() as u16;
+        screen_buffer.default_attributes = screen_buffer.attributes;
+        let mut screen_buffers = BTreeMap::new();
+        screen_buffers.insert(output_handle.0, screen_buffer);
+
         let console = Console {
             handle,
             input_handle,
             output_handle,
             error_handle,
-            screen_buffer: ScreenBuffer {
-                width: 80,
-                height: 25,
-                cursor_x: 0,
-                cursor_y: 0,
-                attributes: FOREGROUND_WHITE,
-                buffer,
-                active: true,
-            },
+            screen_buffers,
+            active_buffer: output_handle,
             input_buffer: VecDeque::new(),
             input_mode: ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT,
-            output_mode: ENABLE_PROCESSED_OUTPUT | ENABLE_WRAP_AT_EOL_OUTPUT,
+            line_editor: LineEditor::new(),
             title: String::from("ReactOS Console"),
-            cursor_info: ConsoleCursorInfo {
-                size: 25,
-                visible: true,
-            },
             window_info: ConsoleWindowInfo {
                 left: 0,
                 top: 0,
@@ -190,18 +943,31 @@ impl ConsoleManager {
                 list.push(1); // Default process ID
                 list
             },
+            attached: false,
         };
-        
+
         self.consoles.insert(handle.0, console);
         self.active_console = Some(handle);
     }
-    
+
     pub fn allocate_handle(&mut self) -> HANDLE {
         let handle = Handle(self.next_handle);
         self.next_handle += 1;
         handle
     }
-    
+
+    fn active_mut(&mut self) -> Option<&mut Console> {
+        let handle = self.active_console?;
+        self.consoles.get_mut(&handle.0)
+    }
+
+    /// Find the console owning an input or output handle
+    fn console_for_handle(&mut self, handle: HANDLE) -> Option<&mut Console> {
+        self.consoles.values_mut().find(|console| {
+            console.input_handle == handle || console.buffer_key(handle).is_some()
+        })
+    }
+
     pub fn get_std_handle(&self, std_handle: i32) -> HANDLE {
         if let Some(console_handle) = self.active_console {
             if let Some(console) = self.consoles.get(&console_handle.0) {
@@ -215,164 +981,278 @@ impl ConsoleManager {
         }
         Handle::INVALID
     }
-    
+
     pub fn alloc_console(&mut self) -> bool {
         if self.active_console.is_some() {
             return false; // Process already has a console
         }
-        
-        self.create_default_console();
-        true
+
+        self.create_default_console();
+        if let Some(console) = self.active_mut() {
+            // A freshly allocated console owns the display
+            console.attached = true;
+            console.present();
+        }
+        true
+    }
+
+    pub fn free_console(&mut self) -> bool {
+        if let Some(handle) = self.active_console {
+            self.consoles.remove(&handle.0);
+            self.active_console = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn write_console(&mut self, handle: HANDLE, data: &[u8]) -> Option<u32> {
+        let console = self.console_for_handle(handle)?;
+        let key = console.buffer_key(handle)?;
+        let is_active = key == console.active_buffer.0;
+        let attached = console.attached;
+        let buffer = console.screen_buffers.get_mut(&key)?;
+
+        if !is_active || attached {
+            let written = buffer.write(data);
+            if is_active {
+                console.present();
+            }
+            return Some(written);
+        }
+
+        // Detached consoles share the kernel writer: what the VT parser
+        // leaves goes there, in the attributes it was written with
+        let mut text = Vec::with_capacity(data.len());
+        for &byte in data {
+            if buffer.write_byte(byte) {
+                text.push((byte, buffer.attributes as u8));
+            }
+        }
+        crate::vga_buffer::write_attributed(&text);
+        Some(data.len() as u32)
+    }
+
+    /// Read from an input handle. Returns Some(0) only for a zero-length
+    /// request; None means no input is available yet.
+    pub fn read_console(&mut self, handle: HANDLE, buffer: &mut [u8], max_count: u32) -> Option<u32> {
+        let console = self.consoles.values_mut().find(|c| c.input_handle == handle)?;
+        let len = core::cmp::min(buffer.len(), max_count as usize);
+        if len == 0 {
+            return Some(0);
+        }
+
+        if console.input_mode & ENABLE_LINE_INPUT != 0 {
+            console.read_line_input(&mut buffer[..len])
+        } else {
+            let read = console.read_raw_input(&mut buffer[..len]);
+            console.present();
+            read
+        }
+    }
+
+    pub fn is_input_handle(&self, handle: HANDLE) -> bool {
+        self.consoles.values().any(|c| c.input_handle == handle)
+    }
+
+    pub fn read_input_records(&mut self, handle: HANDLE, max: usize, remove: bool) -> Option<Vec<InputRecord>> {
+        let console = self.consoles.values_mut().find(|c| c.input_handle == handle)?;
+        let count = core::cmp::min(max, console.input_buffer.len());
+        let records = if remove {
+            console.input_buffer.drain(..count).collect()
+        } else {
+            console.input_buffer.iter().take(count).cloned().collect()
+        };
+        Some(records)
+    }
+
+    pub fn write_input_records(&mut self, handle: HANDLE, records: &[InputRecord]) -> Option<u32> {
+        let console = self.consoles.values_mut().find(|c| c.input_handle == handle)?;
+        for record in records {
+            console.push_input(record.clone());
+        }
+        Some(records.len() as u32)
+    }
+
+    pub fn input_event_count(&self, handle: HANDLE) -> Option<u32> {
+        self.consoles
+            .values()
+            .find(|c| c.input_handle == handle)
+            .map(|c| c.input_buffer.len() as u32)
+    }
+
+    pub fn flush_input(&mut self, handle: HANDLE) -> bool {
+        match self.consoles.values_mut().find(|c| c.input_handle == handle) {
+            Some(console) => {
+                console.input_buffer.clear();
+                console.line_editor.ready.clear();
+                true
+            }
+            None => false,
+        }
     }
-    
-    pub fn free_console(&mut self) -> bool {
-        if let Some(handle) = self.active_console {
-            self.consoles.remove(&handle.0);
-            self.active_console = None;
-            true
-        } else {
-            false
+
+    /// Queue an input record on the active console
+    pub fn queue_input(&mut self, record: InputRecord) {
+        if let Some(console) = self.active_mut() {
+            console.push_input(record);
         }
     }
-    
-    pub fn write_console(&mut self, handle: HANDLE, data: &[u8]) -> Option<u32> {
-        // Find the console that owns this handle and extract the screen buffer
-        let console_key = self.consoles.iter()
-            .find(|(_, console)| console.output_handle == handle || console.error_handle == handle)
-            .map(|(key, _)| *key)?;
-        
-        // Now get the console mutably and write to it
-        if let Some(console) = self.consoles.get_mut(&console_key) {
-            return Some(Self::write_to_screen_buffer(&mut console.screen_buffer, data));
+
+    pub fn get_mode(&self, handle: HANDLE) -> Option<DWORD> {
+        for console in self.consoles.values() {
+            if console.input_handle == handle {
+                return Some(console.input_mode);
+            }
+            if let Some(key) = console.buffer_key(handle) {
+                return console.screen_buffers.get(&key).map(|b| b.output_mode);
+            }
         }
         None
     }
-    
-    fn write_to_screen_buffer(buffer: &mut ScreenBuffer, data: &[u8]) -> u32 {
-        let mut written = 0;
-        
-        for &byte in data {
-            match byte {
-                b'\n' => {
-                    buffer.cursor_y += 1;
-                    buffer.cursor_x = 0;
-                    if buffer.cursor_y >= buffer.height {
-                        Self::scroll_screen_buffer(buffer);
-                        buffer.cursor_y = buffer.height - 1;
-                    }
-                }
-                b'\r' => {
-                    buffer.cursor_x = 0;
-                }
-                b'\t' => {
-                    let tab_size = 8 - (buffer.cursor_x % 8);
-                    for _ in 0..tab_size {
-                        if buffer.cursor_x < buffer.width {
-                            let index = (buffer.cursor_y * buffer.width + buffer.cursor_x) as usize;
-                            if index < buffer.buffer.len() {
-                                buffer.buffer[index] = CharInfo {
-                                    char: b' ' as u16,
-                                    attributes: buffer.attributes,
-                                };
-                            }
-                            buffer.cursor_x += 1;
-                        }
-                    }
-                }
-                b'\x08' => { // Backspace
-                    if buffer.cursor_x > 0 {
-                        buffer.cursor_x -= 1;
-                        let index = (buffer.cursor_y * buffer.width + buffer.cursor_x) as usize;
-                        if index < buffer.buffer.len() {
-                            buffer.buffer[index] = CharInfo {
-                                char: b' ' as u16,
-                                attributes: buffer.attributes,
-                            };
-                        }
-                    }
-                }
-                _ => {
-                    if buffer.cursor_x < buffer.width {
-                        let index = (buffer.cursor_y * buffer.width + buffer.cursor_x) as usize;
-                        if index < buffer.buffer.len() {
-                            buffer.buffer[index] = CharInfo {
-                                char: byte as u16,
-                                attributes: buffer.attributes,
-                            };
-                        }
-                        buffer.cursor_x += 1;
-                        
-                        if buffer.cursor_x >= buffer.width {
-                            buffer.cursor_x = 0;
-                            buffer.cursor_y += 1;
-                            if buffer.cursor_y >= buffer.height {
-                                Self::scroll_screen_buffer(buffer);
-                                buffer.cursor_y = buffer.height - 1;
-                            }
-                        }
-                    }
+
+    pub fn set_mode(&mut self, handle: HANDLE, mode: DWORD) -> bool {
+        for console in self.consoles.values_mut() {
+            if console.input_handle == handle {
+                // Echo is only valid together with line input
+                if mode & ENABLE_ECHO_INPUT != 0 && mode & ENABLE_LINE_INPUT == 0 {
+                    return false;
                 }
+                console.input_mode = mode;
+                return true;
             }
-            
-            written += 1;
-            
-            // Output to VGA buffer for visual feedback
-            if byte.is_ascii_graphic() || byte == b' ' {
-                crate::print!("{}", byte as char);
-            } else if byte == b'\n' {
-                crate::println!();
+            if let Some(key) = console.buffer_key(handle) {
+                if let Some(buffer) = console.screen_buffers.get_mut(&key) {
+                    buffer.output_mode = mode;
+                    return true;
+                }
             }
         }
-        
-        written
+        false
     }
-    
-    fn scroll_screen_buffer(buffer: &mut ScreenBuffer) {
-        let width = buffer.width as usize;
-        let height = buffer.height as usize;
-        
-        // Move all lines up by one
-        for y in 0..(height - 1) {
-            for x in 0..width {
-                let src_index = (y + 1) * width + x;
-                let dst_index = y * width + x;
-                if src_index < buffer.buffer.len() && dst_index < buffer.buffer.len() {
-                    buffer.buffer[dst_index] = buffer.buffer[src_index];
-                }
-            }
+
+    pub fn create_screen_buffer(&mut self) -> Option<HANDLE> {
+        let handle = self.allocate_handle();
+        let console = self.active_mut()?;
+        let (width, height, mode) = console
+            .screen_buffers
+            .get(&console.output_handle.0)
+            .map(|b| (b.width, b.height, b.output_mode))
+            .unwrap_or((80, 25, ENABLE_PROCESSED_OUTPUT | ENABLE_WRAP_AT_EOL_OUTPUT));
+        console.screen_buffers.insert(handle.0, ScreenBuffer::new(width, height, mode));
+        Some(handle)
+    }
+
+    pub fn set_active_screen_buffer(&mut self, handle: HANDLE) -> bool {
+        let Some(console) = self.console_for_handle(handle) else {
+            return false;
+        };
+        let Some(key) = console.buffer_key(handle) else {
+            return false;
+        };
+        if let Some(old) = console.active_buffer_mut() {
+            old.active = false;
         }
-        
-        // Clear the last line
-        for x in 0..width {
-            let index = (height - 1) * width + x;
-            if index < buffer.buffer.len() {
-                buffer.buffer[index] = CharInfo::default();
-            }
+        console.active_buffer = Handle(key);
+        if let Some(new) = console.active_buffer_mut() {
+            new.active = true;
         }
+        // Selecting a buffer explicitly hands it the display
+        console.attached = true;
+        console.present();
+        true
     }
-    
-    pub fn read_console(&mut self, handle: HANDLE, buffer: &mut [u8], max_count: u32) -> Option<u32> {
-        // Find the console that owns this handle
-        for (_, console) in &mut self.consoles {
-            if console.input_handle == handle {
-                // Simple implementation: read from input buffer
-                let mut read = 0;
-                while read < max_count && !console.input_buffer.is_empty() {
-                    if let Some(InputRecord::KeyEvent(key)) = console.input_buffer.pop_front() {
-                        if key.key_down && key.unicode_char != 0 {
-                            if (read as usize) < buffer.len() {
-                                buffer[read as usize] = key.unicode_char as u8;
-                                read += 1;
-                            }
-                        }
+
+    pub fn close_screen_buffer(&mut self, handle: HANDLE) -> bool {
+        for console in self.consoles.values_mut() {
+            // The primary buffer lives as long as the console
+            if handle == console.output_handle || handle == console.error_handle {
+                continue;
+            }
+            if console.screen_buffers.remove(&handle.0).is_some() {
+                if console.active_buffer == handle {
+                    console.active_buffer = console.output_handle;
+                    if let Some(buffer) = console.active_buffer_mut() {
+                        buffer.active = true;
                     }
+                    console.present();
                 }
-                return Some(read);
+                return true;
             }
         }
-        None
+        false
+    }
+
+    pub fn screen_buffer_info(&mut self, handle: HANDLE) -> Option<CONSOLE_SCREEN_BUFFER_INFO> {
+        let console = self.console_for_handle(handle)?;
+        let key = console.buffer_key(handle)?;
+        let window = console.window_info;
+        let buffer = console.screen_buffers.get(&key)?;
+        Some(CONSOLE_SCREEN_BUFFER_INFO {
+            size: Coord { x: buffer.width as i16, y: buffer.height as i16 },
+            cursor_position: Coord { x: buffer.cursor_x as i16, y: buffer.cursor_y as i16 },
+            attributes: buffer.attributes,
+            window,
+            maximum_window_size: Coord { x: buffer.width as i16, y: buffer.height as i16 },
+        })
+    }
+
+    pub fn set_cursor_info(&mut self, handle: HANDLE, info: ConsoleCursorInfo) -> bool {
+        if info.size == 0 || info.size > 100 {
+            return false;
+        }
+        let Some(console) = self.console_for_handle(handle) else {
+            return false;
+        };
+        let Some(key) = console.buffer_key(handle) else {
+            return false;
+        };
+        if let Some(buffer) = console.screen_buffers.get_mut(&key) {
+            buffer.cursor_info = info;
+        }
+        if key == console.active_buffer.0 {
+            console.present();
+        }
+        true
+    }
+
+    pub fn get_cursor_info(&mut self, handle: HANDLE) -> Option<ConsoleCursorInfo> {
+        let console = self.console_for_handle(handle)?;
+        let key = console.buffer_key(handle)?;
+        console.screen_buffers.get(&key).map(|b| b.cursor_info)
+    }
+
+    /// Fill a run of cells with a character and/or attribute
+    pub fn fill_output(
+        &mut self,
+        handle: HANDLE,
+        ch: Option<u16>,
+        attributes: Option<u16>,
+        length: u32,
+        coord: Coord,
+    ) -> Option<u32> {
+        let console = self.console_for_handle(handle)?;
+        let key = console.buffer_key(handle)?;
+        let buffer = console.screen_buffers.get_mut(&key)?;
+        if coord.x < 0 || coord.y < 0 || coord.x as u16 >= buffer.width || coord.y as u16 >= buffer.height {
+            return None;
+        }
+        let start = buffer.index(coord.x as u16, coord.y as u16);
+        let end = core::cmp::min(start + length as usize, buffer.buffer.len());
+        for cell in &mut buffer.buffer[start..end] {
+            if let Some(c) = ch {
+                cell.char = c;
+            }
+            if let Some(a) = attributes {
+                cell.attributes = a;
+            }
+        }
+        if key == console.active_buffer.0 {
+            console.present();
+        }
+        Some((end - start) as u32)
     }
-    
+
     pub fn set_console_title(&mut self, title: &str) -> bool {
         if let Some(handle) = self.active_console {
             if let Some(console) = self.consoles.get_mut(&handle.0) {
@@ -382,7 +1262,7 @@ impl ConsoleManager {
         }
         false
     }
-    
+
     pub fn get_console_title(&self) -> Option<String> {
         if let Some(handle) = self.active_console {
             if let Some(console) = self.consoles.get(&handle.0) {
@@ -391,32 +1271,109 @@ impl ConsoleManager {
         }
         None
     }
-    
+
     pub fn set_console_text_attribute(&mut self, handle: HANDLE, attributes: u16) -> bool {
-        for (_, console) in &mut self.consoles {
-            if console.output_handle == handle {
-                console.screen_buffer.attributes = attributes;
-                return true;
+        let Some(console) = self.console_for_handle(handle) else {
+            return false;
+        };
+        let Some(key) = console.buffer_key(handle) else {
+            return false;
+        };
+        match console.screen_buffers.get_mut(&key) {
+            Some(buffer) => {
+                buffer.attributes = attributes;
+                true
             }
+            None => false,
+        }
+    }
+
+    /// Put a screen buffer's text attributes back to its defaults
+    pub fn reset_text_attribute(&mut self, handle: HANDLE) -> bool {
+        let Some(console) = self.console_for_handle(handle) else {
+            return false;
+        };
+        let Some(key) = console.buffer_key(handle) else {
+            return false;
+        };
+        match console.screen_buffers.get_mut(&key) {
+            Some(buffer) => {
+                buffer.attributes = buffer.default_attributes;
+                true
+            }
+            None => false,
         }
-        false
     }
-    
+
     pub fn set_console_cursor_position(&mut self, handle: HANDLE, coord: Coord) -> bool {
-        for (_, console) in &mut self.consoles {
-            if console.output_handle == handle {
-                if coord.x >= 0 && coord.x < console.screen_buffer.width as i16 &&
-                   coord.y >= 0 && coord.y < console.screen_buffer.height as i16 {
-                    console.screen_buffer.cursor_x = coord.x as u16;
-                    console.screen_buffer.cursor_y = coord.y as u16;
-                    return true;
-                }
+        let Some(console) = self.console_for_handle(handle) else {
+            return false;
+        };
+        let Some(key) = console.buffer_key(handle) else {
+            return false;
+        };
+        let Some(buffer) = console.screen_buffers.get_mut(&key) else {
+            return false;
+        };
+        if coord.x >= 0 && coord.x < buffer.width as i16 &&
+           coord.y >= 0 && coord.y < buffer.height as i16 {
+            buffer.cursor_x = coord.x as u16;
+            buffer.cursor_y = coord.y as u16;
+            if key == console.active_buffer.0 {
+                console.present();
             }
+            return true;
         }
         false
     }
 }
 
+/// Write the shell's output to the active console's output buffer, as
+/// WriteConsoleA would. False if there is no console, and the caller should
+/// use the kernel writer itself.
+pub fn write_shell(data: &[u8]) -> bool {
+    let mut manager = CONSOLE_MANAGER.lock();
+    let handle = manager.get_std_handle(STD_OUTPUT_HANDLE);
+    manager.write_console(handle, data).is_some()
+}
+
+/// Feed a decoded key from the keyboard driver into the active console's
+/// input queue. Called from interrupt context, so the manager is only
+/// try-locked; a contended key is dropped rather than deadlocking.
+pub fn queue_key(ch: char, virtual_key_code: u16, control_key_state: DWORD) {
+    let unicode_char = if (ch as u32) < 0x10000 { ch as u32 as u16 } else { 0 };
+    let virtual_key_code = if virtual_key_code != 0 {
+        virtual_key_code
+    } else {
+        char_to_virtual_key(ch)
+    };
+    let record = |key_down| InputRecord::KeyEvent(KeyEventRecord {
+        key_down,
+        repeat_count: 1,
+        virtual_key_code,
+        virtual_scan_code: 0,
+        unicode_char,
+        control_key_state,
+    });
+
+    if let Some(mut manager) = CONSOLE_MANAGER.try_lock() {
+        manager.queue_input(record(true));
+        manager.queue_input(record(false));
+    }
+}
+
+fn char_to_virtual_key(ch: char) -> u16 {
+    match ch {
+        '\n' | '\r' => VK_RETURN,
+        '\x08' => VK_BACK,
+        '\t' => VK_TAB,
+        '\x1b' => VK_ESCAPE,
+        'a'..='z' => ch.to_ascii_uppercase() as u16,
+        'A'..='Z' | '0'..='9' | ' ' => ch as u16,
+        _ => 0,
+    }
+}
+
 // Console color attributes
 pub const FOREGROUND_BLUE: u16 = 0x0001;
 pub const FOREGROUND_GREEN: u16 = 0x0002;
@@ -438,9 +1395,46 @@ pub const ENABLE_WINDOW_INPUT: DWORD = 0x0008;
 pub const ENABLE_MOUSE_INPUT: DWORD = 0x0010;
 pub const ENABLE_INSERT_MODE: DWORD = 0x0020;
 pub const ENABLE_QUICK_EDIT_MODE: DWORD = 0x0040;
+pub const ENABLE_VIRTUAL_TERMINAL_INPUT: DWORD = 0x0200;
 
 pub const ENABLE_PROCESSED_OUTPUT: DWORD = 0x0001;
 pub const ENABLE_WRAP_AT_EOL_OUTPUT: DWORD = 0x0002;
+pub const ENABLE_VIRTUAL_TERMINAL_PROCESSING: DWORD = 0x0004;
+
+// Input record event types
+pub const KEY_EVENT: u16 = 0x0001;
+pub const MOUSE_EVENT: u16 = 0x0002;
+pub const WINDOW_BUFFER_SIZE_EVENT: u16 = 0x0004;
+pub const MENU_EVENT: u16 = 0x0008;
+pub const FOCUS_EVENT: u16 = 0x0010;
+
+// Virtual key codes used by the line editor
+pub const VK_BACK: u16 = 0x08;
+pub const VK_TAB: u16 = 0x09;
+pub const VK_RETURN: u16 = 0x0D;
+pub const VK_ESCAPE: u16 = 0x1B;
+pub const VK_END: u16 = 0x23;
+pub const VK_HOME: u16 = 0x24;
+pub const VK_LEFT: u16 = 0x25;
+pub const VK_UP: u16 = 0x26;
+pub const VK_RIGHT: u16 = 0x27;
+pub const VK_DOWN: u16 = 0x28;
+pub const VK_INSERT: u16 = 0x2D;
+pub const VK_DELETE: u16 = 0x2E;
+
+// Control key state flags
+pub const RIGHT_ALT_PRESSED: DWORD = 0x0001;
+pub const LEFT_ALT_PRESSED: DWORD = 0x0002;
+pub const RIGHT_CTRL_PRESSED: DWORD = 0x0004;
+pub const LEFT_CTRL_PRESSED: DWORD = 0x0008;
+pub const SHIFT_PRESSED: DWORD = 0x0010;
+pub const CAPSLOCK_ON: DWORD = 0x0080;
+
+// Console buffer limits
+pub const CONSOLE_HISTORY_SIZE: usize = 50;
+pub const CONSOLE_INPUT_LIMIT: usize = 256;
+
+pub const CONSOLE_TEXTMODE_BUFFER: DWORD = 1;
 
 // Standard handle constants
 pub const STD_INPUT_HANDLE: i32 = -10;
@@ -487,9 +1481,9 @@ pub extern "C" fn WriteConsoleA(
     if buffer.is_null() {
         return 0;
     }
-    
+
     let data = unsafe { core::slice::from_raw_parts(buffer, chars_to_write as usize) };
-    
+
     if let Some(written) = CONSOLE_MANAGER.lock().write_console(handle, data) {
         if !chars_written.is_null() {
             unsafe {
@@ -502,7 +1496,7 @@ pub extern "C" fn WriteConsoleA(
     }
 }
 
-/// ReadConsoleA - Read from console input
+/// ReadConsoleA - Read from console input, blocking until input is available
 #[no_mangle]
 pub extern "C" fn ReadConsoleA(
     handle: HANDLE,
@@ -514,26 +1508,285 @@ pub extern "C" fn ReadConsoleA(
     if buffer.is_null() {
         return 0;
     }
-    
-    let mut data = unsafe { core::slice::from_raw_parts_mut(buffer, chars_to_read as usize) };
-    
-    if let Some(read) = CONSOLE_MANAGER.lock().read_console(handle, &mut data, chars_to_read) {
-        if !chars_read.is_null() {
+
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer, chars_to_read as usize) };
+
+    if !CONSOLE_MANAGER.lock().is_input_handle(handle) {
+        return 0;
+    }
+
+    // The manager lock is dropped between attempts so the keyboard interrupt
+    // can queue more input
+    let read = loop {
+        if let Some(read) = CONSOLE_MANAGER.lock().read_console(handle, data, chars_to_read) {
+            break read;
+        }
+        x86_64::instructions::hlt();
+    };
+
+    if !chars_read.is_null() {
+        unsafe {
+            *chars_read = read;
+        }
+    }
+    1
+}
+
+/// ReadConsoleInputA - Read input records, blocking until at least one is available
+#[no_mangle]
+pub extern "C" fn ReadConsoleInputA(
+    handle: HANDLE,
+    buffer: *mut INPUT_RECORD,
+    length: DWORD,
+    events_read: *mut DWORD,
+) -> BOOL {
+    if buffer.is_null() || length == 0 {
+        return 0;
+    }
+
+    let records = loop {
+        match CONSOLE_MANAGER.lock().read_input_records(handle, length as usize, true) {
+            None => return 0,
+            Some(records) if !records.is_empty() => break records,
+            Some(_) => {}
+        }
+        x86_64::instructions::hlt();
+    };
+
+    copy_input_records(&records, buffer, events_read);
+    1
+}
+
+/// PeekConsoleInputA - Read input records without removing them
+#[no_mangle]
+pub extern "C" fn PeekConsoleInputA(
+    handle: HANDLE,
+    buffer: *mut INPUT_RECORD,
+    length: DWORD,
+    events_read: *mut DWORD,
+) -> BOOL {
+    if buffer.is_null() {
+        return 0;
+    }
+
+    match CONSOLE_MANAGER.lock().read_input_records(handle, length as usize, false) {
+        Some(records) => {
+            copy_input_records(&records, buffer, events_read);
+            1
+        }
+        None => 0,
+    }
+}
+
+fn copy_input_records(records: &[InputRecord], buffer: *mut INPUT_RECORD, events_read: *mut DWORD) {
+    for (i, record) in records.iter().enumerate() {
+        unsafe {
+            *buffer.add(i) = record.to_c();
+        }
+    }
+    if !events_read.is_null() {
+        unsafe {
+            *events_read = records.len() as DWORD;
+        }
+    }
+}
+
+/// GetNumberOfConsoleInputEvents - Count unread input records
+#[no_mangle]
+pub extern "C" fn GetNumberOfConsoleInputEvents(handle: HANDLE, count: *mut DWORD) -> BOOL {
+    match CONSOLE_MANAGER.lock().input_event_count(handle) {
+        Some(n) => {
+            if !count.is_null() {
+                unsafe {
+                    *count = n;
+                }
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
+/// FlushConsoleInputBuffer - Discard all pending input
+#[no_mangle]
+pub extern "C" fn FlushConsoleInputBuffer(handle: HANDLE) -> BOOL {
+    if CONSOLE_MANAGER.lock().flush_input(handle) {
+        1
+    } else {
+        0
+    }
+}
+
+/// GetConsoleMode - Get the input or output mode of a console handle
+#[no_mangle]
+pub extern "C" fn GetConsoleMode(handle: HANDLE, mode: *mut DWORD) -> BOOL {
+    if mode.is_null() {
+        return 0;
+    }
+
+    match CONSOLE_MANAGER.lock().get_mode(handle) {
+        Some(m) => {
+            unsafe {
+                *mode = m;
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
+/// SetConsoleMode - Set the input or output mode of a console handle
+#[no_mangle]
+pub extern "C" fn SetConsoleMode(handle: HANDLE, mode: DWORD) -> BOOL {
+    if CONSOLE_MANAGER.lock().set_mode(handle, mode) {
+        1
+    } else {
+        0
+    }
+}
+
+/// CreateConsoleScreenBuffer - Create an additional screen buffer
+#[no_mangle]
+pub extern "C" fn CreateConsoleScreenBuffer(
+    _desired_access: DWORD,
+    _share_mode: DWORD,
+    _security_attributes: *const u8,
+    flags: DWORD,
+    _screen_buffer_data: *const u8,
+) -> HANDLE {
+    if flags != CONSOLE_TEXTMODE_BUFFER {
+        return Handle::INVALID;
+    }
+
+    CONSOLE_MANAGER.lock().create_screen_buffer().unwrap_or(Handle::INVALID)
+}
+
+/// SetConsoleActiveScreenBuffer - Make a screen buffer the displayed one
+#[no_mangle]
+pub extern "C" fn SetConsoleActiveScreenBuffer(handle: HANDLE) -> BOOL {
+    if CONSOLE_MANAGER.lock().set_active_screen_buffer(handle) {
+        1
+    } else {
+        0
+    }
+}
+
+/// CloseConsoleScreenBuffer - Release a screen buffer created by CreateConsoleScreenBuffer
+pub fn close_screen_buffer(handle: HANDLE) -> bool {
+    CONSOLE_MANAGER.lock().close_screen_buffer(handle)
+}
+
+/// GetConsoleScreenBufferInfo - Get size, cursor and attributes of a buffer
+#[no_mangle]
+pub extern "C" fn GetConsoleScreenBufferInfo(
+    handle: HANDLE,
+    info: *mut CONSOLE_SCREEN_BUFFER_INFO,
+) -> BOOL {
+    if info.is_null() {
+        return 0;
+    }
+
+    match CONSOLE_MANAGER.lock().screen_buffer_info(handle) {
+        Some(i) => {
             unsafe {
-                *chars_read = read;
+                *info = i;
             }
+            1
         }
+        None => 0,
+    }
+}
+
+/// SetConsoleCursorInfo - Set cursor size and visibility
+#[no_mangle]
+pub extern "C" fn SetConsoleCursorInfo(handle: HANDLE, info: *const CONSOLE_CURSOR_INFO) -> BOOL {
+    if info.is_null() {
+        return 0;
+    }
+
+    let info = unsafe { &*info };
+    let cursor_info = ConsoleCursorInfo {
+        size: info.size,
+        visible: info.visible != 0,
+    };
+
+    if CONSOLE_MANAGER.lock().set_cursor_info(handle, cursor_info) {
         1
     } else {
         0
     }
 }
 
+/// GetConsoleCursorInfo - Get cursor size and visibility
+#[no_mangle]
+pub extern "C" fn GetConsoleCursorInfo(handle: HANDLE, info: *mut CONSOLE_CURSOR_INFO) -> BOOL {
+    if info.is_null() {
+        return 0;
+    }
+
+    match CONSOLE_MANAGER.lock().get_cursor_info(handle) {
+        Some(cursor_info) => {
+            unsafe {
+                *info = CONSOLE_CURSOR_INFO {
+                    size: cursor_info.size,
+                    visible: cursor_info.visible as BOOL,
+                };
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
+/// FillConsoleOutputCharacterA - Write a character repeatedly
+#[no_mangle]
+pub extern "C" fn FillConsoleOutputCharacterA(
+    handle: HANDLE,
+    character: u8,
+    length: DWORD,
+    coord: Coord,
+    written: *mut DWORD,
+) -> BOOL {
+    match CONSOLE_MANAGER.lock().fill_output(handle, Some(character as u16), None, length, coord) {
+        Some(count) => {
+            if !written.is_null() {
+                unsafe {
+                    *written = count;
+                }
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
+/// FillConsoleOutputAttribute - Set attributes for a run of cells
+#[no_mangle]
+pub extern "C" fn FillConsoleOutputAttribute(
+    handle: HANDLE,
+    attribute: u16,
+    length: DWORD,
+    coord: Coord,
+    written: *mut DWORD,
+) -> BOOL {
+    match CONSOLE_MANAGER.lock().fill_output(handle, None, Some(attribute), length, coord) {
+        Some(count) => {
+            if !written.is_null() {
+                unsafe {
+                    *written = count;
+                }
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
 /// SetConsoleTitleA - Set console window title
 #[no_mangle]
 pub extern "C" fn SetConsoleTitleA(title: LPCSTR) -> BOOL {
     use core::ffi::CStr;
-    
+
     let title_str = if title.is_null() {
         ""
     } else {
@@ -542,7 +1795,7 @@ pub extern "C" fn SetConsoleTitleA(title: LPCSTR) -> BOOL {
             Err(_) => return 0,
         }
     };
-    
+
     if CONSOLE_MANAGER.lock().set_console_title(title_str) {
         1
     } else {
@@ -556,16 +1809,16 @@ pub extern "C" fn GetConsoleTitleA(title: LPSTR, size: DWORD) -> DWORD {
     if title.is_null() || size == 0 {
         return 0;
     }
-    
+
     if let Some(console_title) = CONSOLE_MANAGER.lock().get_console_title() {
         let bytes = console_title.as_bytes();
         let copy_len = core::cmp::min(bytes.len(), (size - 1) as usize);
-        
+
         unsafe {
             core::ptr::copy_nonoverlapping(bytes.as_ptr(), title, copy_len);
             *title.add(copy_len) = 0; // Null terminate
         }
-        
+
         copy_len as DWORD
     } else {
         0
@@ -590,4 +1843,4 @@ pub extern "C" fn SetConsoleCursorPosition(handle: HANDLE, coord: Coord) -> BOOL
     } else {
         0
     }
-}
\ No newline at end of file
+}
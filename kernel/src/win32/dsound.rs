@@ -0,0 +1,1422 @@
+//! DirectSound
+//!
+//! DirectSoundCreate8 opens the sound card for an app, which makes sound
+//! buffers on it. Secondary buffers hold samples in the app's own format;
+//! the app fills them through Lock and plays them once or looping. The
+//! primary buffer stands for the output: its volume and pan are the sound
+//! mixer's master control, and its format is kept for GetFormat, but the
+//! mix always goes to the card at 48 kHz stereo 16-bit. It cannot be
+//! written, so DSSCL_WRITEPRIMARY is refused.
+//!
+//! While anything plays an hrtimer mixes every 10 ms. Each playing buffer
+//! is read at its frequency, interpolated to the output rate, given its
+//! volume and pan, and summed through the mixer's PCM channel. A buffer's
+//! play cursor is where the last mix started reading it and its write
+//! cursor where it stopped, so the app may write anywhere outside the two.
+//! Position notifications signal their events as the mix reads past their
+//! offsets, and DSBPN_OFFSETSTOP ones when the buffer stops.
+//!
+//! There is no 3D and there are no effects: IDirectSound3DBuffer is not
+//! offered and SetFX fails. A duplicate shares its original's samples but
+//! has its own cursors, volume and pan.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem::{offset_of, size_of};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::ole32::{E_INVALIDARG, E_NOINTERFACE, E_NOTIMPL, E_POINTER, GUID, HRESULT, LPVOID, REFIID, S_OK};
+use super::{Handle, BOOL, LPCSTR, LPCWSTR};
+use crate::drivers::audio::DSCaps;
+use crate::sound::mixer::{AudioMixer, MixerChannel};
+use crate::sound::AUDIO_MANAGER;
+use crate::time::{clocksource::now_ns, hrtimer};
+
+pub const DS_OK: HRESULT = S_OK;
+pub const DSERR_ALLOCATED: HRESULT = 0x8878000Au32 as i32;
+pub const DSERR_CONTROLUNAVAIL: HRESULT = 0x8878001Eu32 as i32;
+pub const DSERR_INVALIDPARAM: HRESULT = E_INVALIDARG;
+pub const DSERR_INVALIDCALL: HRESULT = 0x88780032u32 as i32;
+pub const DSERR_PRIOLEVELNEEDED: HRESULT = 0x88780046u32 as i32;
+pub const DSERR_BADFORMAT: HRESULT = 0x88780064u32 as i32;
+pub const DSERR_UNSUPPORTED: HRESULT = E_NOTIMPL;
+pub const DSERR_NODRIVER: HRESULT = 0x88780078u32 as i32;
+pub const DSERR_ALREADYINITIALIZED: HRESULT = 0x88780082u32 as i32;
+pub const DSERR_NOAGGREGATION: HRESULT = 0x80040110u32 as i32;
+pub const DSERR_OBJECTNOTFOUND: HRESULT = 0x88781161u32 as i32;
+
+// Sound buffer flags
+pub const DSBCAPS_PRIMARYBUFFER: u32 = 0x0000_0001;
+pub const DSBCAPS_STATIC: u32 = 0x0000_0002;
+pub const DSBCAPS_LOCHARDWARE: u32 = 0x0000_0004;
+pub const DSBCAPS_LOCSOFTWARE: u32 = 0x0000_0008;
+pub const DSBCAPS_CTRL3D: u32 = 0x0000_0010;
+pub const DSBCAPS_CTRLFREQUENCY: u32 = 0x0000_0020;
+pub const DSBCAPS_CTRLPAN: u32 = 0x0000_0040;
+pub const DSBCAPS_CTRLVOLUME: u32 = 0x0000_0080;
+pub const DSBCAPS_CTRLPOSITIONNOTIFY: u32 = 0x0000_0100;
+pub const DSBCAPS_CTRLFX: u32 = 0x0000_0200;
+pub const DSBCAPS_GETCURRENTPOSITION2: u32 = 0x0001_0000;
+
+pub const DSBPLAY_LOOPING: u32 = 0x1;
+
+pub const DSBSTATUS_PLAYING: u32 = 0x1;
+pub const DSBSTATUS_LOOPING: u32 = 0x4;
+pub const DSBSTATUS_LOCSOFTWARE: u32 = 0x8;
+
+pub const DSBLOCK_FROMWRITECURSOR: u32 = 0x1;
+pub const DSBLOCK_ENTIREBUFFER: u32 = 0x2;
+
+// Cooperative levels
+pub const DSSCL_NORMAL: u32 = 1;
+pub const DSSCL_PRIORITY: u32 = 2;
+pub const DSSCL_EXCLUSIVE: u32 = 3;
+pub const DSSCL_WRITEPRIMARY: u32 = 4;
+
+pub const DSBVOLUME_MIN: i32 = -10_000;
+pub const DSBVOLUME_MAX: i32 = 0;
+pub const DSBPAN_LEFT: i32 = -10_000;
+pub const DSBPAN_RIGHT: i32 = 10_000;
+pub const DSBFREQUENCY_ORIGINAL: u32 = 0;
+pub const DSBFREQUENCY_MIN: u32 = 100;
+pub const DSBFREQUENCY_MAX: u32 = 200_000;
+pub const DSBSIZE_MIN: u32 = 4;
+pub const DSBSIZE_MAX: u32 = 0x0FFF_FFFF;
+
+pub const DSBPN_OFFSETSTOP: u32 = 0xFFFF_FFFF;
+
+pub const DSSPEAKER_STEREO: u32 = 4;
+
+// Device caps flags
+const DSCAPS_PRIMARYMONO: u32 = 0x001;
+const DSCAPS_PRIMARYSTEREO: u32 = 0x002;
+const DSCAPS_PRIMARY8BIT: u32 = 0x004;
+const DSCAPS_PRIMARY16BIT: u32 = 0x008;
+const DSCAPS_CONTINUOUSRATE: u32 = 0x010;
+const DSCAPS_EMULDRIVER: u32 = 0x020;
+const DSCAPS_SECONDARYMONO: u32 = 0x100;
+const DSCAPS_SECONDARYSTEREO: u32 = 0x200;
+const DSCAPS_SECONDARY8BIT: u32 = 0x400;
+const DSCAPS_SECONDARY16BIT: u32 = 0x800;
+
+// What VerifyCertification reports
+const DS_UNCERTIFIED: u32 = 1;
+
+pub const WAVE_FORMAT_PCM: u16 = 1;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// Where WAVEFORMATEXTENSIBLE's sub-format GUID starts, and the least extra
+// size that holds it
+const SUBFORMAT_OFFSET: usize = 24;
+const EXTENSIBLE_EXTRA: u16 = 22;
+
+pub const IID_IDIRECTSOUND: GUID =
+    GUID { data1: 0x279AFA83, data2: 0x4981, data3: 0x11CE, data4: [0xA5, 0x21, 0x00, 0x20, 0xAF, 0x0B, 0xE5, 0x60] };
+pub const IID_IDIRECTSOUND8: GUID =
+    GUID { data1: 0xC50A7E93, data2: 0xF395, data3: 0x4834, data4: [0x9E, 0xF6, 0x7F, 0xA9, 0x9D, 0xE5, 0x09, 0x66] };
+pub const IID_IDIRECTSOUNDBUFFER: GUID =
+    GUID { data1: 0x279AFA85, data2: 0x4981, data3: 0x11CE, data4: [0xA5, 0x21, 0x00, 0x20, 0xAF, 0x0B, 0xE5, 0x60] };
+pub const IID_IDIRECTSOUNDBUFFER8: GUID =
+    GUID { data1: 0x6825A449, data2: 0x7524, data3: 0x4D82, data4: [0x92, 0x0F, 0x50, 0xE3, 0x6A, 0xB3, 0xAB, 0x1E] };
+pub const IID_IDIRECTSOUNDNOTIFY: GUID =
+    GUID { data1: 0xB0210783, data2: 0x89CD, data3: 0x11D0, data4: [0xAF, 0x08, 0x00, 0xA0, 0xC9, 0x25, 0xCD, 0x16] };
+pub const DSDEVID_DEFAULT_PLAYBACK: GUID =
+    GUID { data1: 0xDEF00000, data2: 0x9C6D, data3: 0x47ED, data4: [0xAA, 0xF1, 0x4D, 0xDA, 0x8F, 0x2B, 0x5C, 0x03] };
+pub const DSDEVID_DEFAULT_VOICE_PLAYBACK: GUID =
+    GUID { data1: 0xDEF00002, data2: 0x9C6D, data3: 0x47ED, data4: [0xAA, 0xF1, 0x4D, 0xDA, 0x8F, 0x2B, 0x5C, 0x03] };
+
+// How DirectSoundEnumerate names the sound card
+const CARD_GUID: GUID =
+    GUID { data1: 0x5D2B6F10, data2: 0x0001, data3: 0x4F0A, data4: [0x80, 0x52, 0x4F, 0x53, 0x44, 0x53, 0x4E, 0x44] };
+
+// The mix goes out at the rate, in stereo, every period
+const OUTPUT_RATE: u32 = 48_000;
+const MIX_PERIOD_NS: u64 = 10_000_000;
+// The most a late tick catches up on; beyond it the gap is skipped
+const MAX_CATCH_UP_NS: u64 = 100_000_000;
+
+// One decibel down, as a gain
+const DECIBEL: f32 = 0.891_250_9;
+
+/// WAVEFORMATEX, packed as mmsystem.h has it
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WaveFormatEx {
+    pub format_tag: u16,
+    pub channels: u16,
+    pub samples_per_sec: u32,
+    pub avg_bytes_per_sec: u32,
+    pub block_align: u16,
+    pub bits_per_sample: u16,
+    pub size: u16,
+}
+
+/// DSBUFFERDESC
+#[repr(C)]
+pub struct BufferDesc {
+    pub size: u32,
+    pub flags: u32,
+    pub buffer_bytes: u32,
+    pub reserved: u32,
+    pub format: *const WaveFormatEx,
+    /// Only in the DirectX 7 form, which `size` tells apart
+    pub algorithm_3d: GUID,
+}
+
+/// DSBCAPS
+#[repr(C)]
+pub struct BufferCaps {
+    pub size: u32,
+    pub flags: u32,
+    pub buffer_bytes: u32,
+    pub unlock_transfer_rate: u32,
+    pub play_cpu_overhead: u32,
+}
+
+/// DSBPOSITIONNOTIFY
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PositionNotify {
+    pub offset: u32,
+    pub event: Handle,
+}
+
+pub type EnumCallbackA = extern "system" fn(*mut GUID, LPCSTR, LPCSTR, LPVOID) -> BOOL;
+pub type EnumCallbackW = extern "system" fn(*mut GUID, LPCWSTR, LPCWSTR, LPVOID) -> BOOL;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Format {
+    channels: u16,
+    rate: u32,
+    bits: u16,
+    float: bool,
+}
+
+impl Format {
+    // What the primary buffer starts as, as on Windows
+    const PRIMARY: Format = Format { channels: 2, rate: 22_050, bits: 8, float: false };
+
+    // A WAVEFORMATEX or WAVEFORMATEXTENSIBLE, if it is PCM or float that
+    // can be mixed
+    unsafe fn read(wave: *const WaveFormatEx) -> Option<Format> {
+        if wave.is_null() {
+            return None;
+        }
+        let header = wave.read_unaligned();
+        let mut tag = header.format_tag;
+        if tag == WAVE_FORMAT_EXTENSIBLE && header.size >= EXTENSIBLE_EXTRA {
+            // The sub-format GUID's first field is the tag it stands for
+            tag = wave.cast::<u8>().add(SUBFORMAT_OFFSET).cast::<u32>().read_unaligned() as u16;
+        }
+        let format = Format {
+            channels: header.channels,
+            rate: header.samples_per_sec,
+            bits: header.bits_per_sample,
+            float: tag == WAVE_FORMAT_IEEE_FLOAT,
+        };
+        let bits = match tag {
+            WAVE_FORMAT_PCM => matches!(format.bits, 8 | 16 | 24 | 32),
+            WAVE_FORMAT_IEEE_FLOAT => format.bits == 32,
+            _ => false,
+        };
+        let valid = bits
+            && format.channels > 0
+            && (DSBFREQUENCY_MIN..=DSBFREQUENCY_MAX).contains(&format.rate)
+            && header.block_align as u32 == format.block_align();
+        valid.then_some(format)
+    }
+
+    fn block_align(&self) -> u32 {
+        self.channels as u32 * (self.bits as u32 / 8)
+    }
+
+    fn wave(&self) -> WaveFormatEx {
+        let block_align = self.block_align();
+        WaveFormatEx {
+            format_tag: if self.float { WAVE_FORMAT_IEEE_FLOAT } else { WAVE_FORMAT_PCM },
+            channels: self.channels,
+            samples_per_sec: self.rate,
+            avg_bytes_per_sec: self.rate * block_align,
+            block_align: block_align as u16,
+            bits_per_sample: self.bits,
+            size: 0,
+        }
+    }
+
+    // The sample at `offset` in `data`, from -1 to 1
+    fn sample(&self, data: &[u8], offset: usize) -> f32 {
+        let bytes = &data[offset..offset + self.bits as usize / 8];
+        match (self.bits, self.float) {
+            (8, _) => (bytes[0] as f32 - 128.0) / 128.0,
+            (16, _) => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32_768.0,
+            (24, _) => (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as f32 / 8_388_608.0,
+            (_, true) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).clamp(-1.0, 1.0),
+            _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2_147_483_648.0,
+        }
+    }
+}
+
+// A level in hundredths of a decibel as a gain; DSBVOLUME_MIN and below
+// are silence
+fn gain(millibels: i32) -> f32 {
+    if millibels <= DSBVOLUME_MIN {
+        return 0.0;
+    }
+    let attenuation = millibels.min(0).unsigned_abs();
+    let mut gain = 1.0;
+    for _ in 0..attenuation / 100 {
+        gain *= DECIBEL;
+    }
+    gain * (1.0 - (1.0 - DECIBEL) * (attenuation % 100) as f32 / 100.0)
+}
+
+// A buffer's samples, shared with its duplicates. The app writes them
+// through the pointers Lock hands out while the mix reads them, so they
+// are only reached through a raw pointer
+struct Samples {
+    data: *mut u8,
+    len: usize,
+}
+
+// The samples are plain bytes; racing writes only garble the sound
+unsafe impl Send for Samples {}
+unsafe impl Sync for Samples {}
+
+impl Samples {
+    fn new(len: usize) -> Samples {
+        let data = Box::into_raw(vec![0u8; len].into_boxed_slice()).cast::<u8>();
+        Samples { data, len }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for Samples {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(core::ptr::slice_from_raw_parts_mut(self.data, self.len))) }
+    }
+}
+
+struct Buffer {
+    device: u64,
+    flags: u32,
+    format: Format,
+    // None for the primary buffer, which holds no samples
+    samples: Option<Arc<Samples>>,
+    volume: i32,
+    pan: i32,
+    frequency: u32,
+    // Left and right gains from the volume and pan
+    gains: (f32, f32),
+    playing: bool,
+    looping: bool,
+    // Where the mix reads next, in frames with a 32-bit fraction
+    position: u64,
+    // Where the last mix started reading, in bytes
+    play_cursor: u32,
+    notifications: Vec<PositionNotify>,
+}
+
+impl Buffer {
+    fn new(device: u64, flags: u32, format: Format, samples: Option<Arc<Samples>>) -> Buffer {
+        Buffer {
+            device,
+            flags,
+            format,
+            samples,
+            volume: DSBVOLUME_MAX,
+            pan: 0,
+            frequency: format.rate,
+            gains: (1.0, 1.0),
+            playing: false,
+            looping: false,
+            position: 0,
+            play_cursor: 0,
+            notifications: Vec::new(),
+        }
+    }
+
+    fn is_primary(&self) -> bool {
+        self.flags & DSBCAPS_PRIMARYBUFFER != 0
+    }
+
+    // The primary buffer's size is 100 ms of its format
+    fn size(&self) -> u32 {
+        match &self.samples {
+            Some(samples) => samples.len as u32,
+            None => self.format.rate / 10 * self.format.block_align(),
+        }
+    }
+
+    fn require(&self, control: u32) -> Result<(), HRESULT> {
+        if self.flags & control == 0 {
+            return Err(DSERR_CONTROLUNAVAIL);
+        }
+        Ok(())
+    }
+
+    fn write_cursor(&self) -> u32 {
+        let offset = (self.position >> 32) * self.format.block_align() as u64;
+        (offset % self.size().max(1) as u64) as u32
+    }
+
+    // The play and write cursors. The primary buffer's follow the output
+    fn cursors(&self) -> (u32, u32) {
+        if self.is_primary() {
+            let align = self.format.block_align() as u64;
+            let frames = MIXED_FRAMES.load(Ordering::Relaxed) * self.format.rate as u64 / OUTPUT_RATE as u64;
+            let size = self.size().max(1) as u64;
+            let period = self.format.rate as u64 * align * MIX_PERIOD_NS / 1_000_000_000;
+            return (((frames * align) % size) as u32, ((frames * align + period) % size) as u32);
+        }
+        let write = self.write_cursor();
+        (if self.playing { self.play_cursor } else { write }, write)
+    }
+
+    fn update_gains(&mut self) {
+        self.gains = (gain(self.volume - self.pan.max(0)), gain(self.volume + self.pan.min(0)));
+    }
+
+    fn frame(&self, data: &[u8], offset: usize) -> (f32, f32) {
+        let left = self.format.sample(data, offset);
+        if self.format.channels == 1 {
+            return (left, left);
+        }
+        (left, self.format.sample(data, offset + self.format.bits as usize / 8))
+    }
+
+    // Mix the buffer's next `out.len() / 2` frames into stereo `out`,
+    // adding the events its notifications signal to `signalled`
+    fn render(&mut self, out: &mut [f32], signalled: &mut Vec<Handle>) {
+        let Some(samples) = self.samples.clone() else {
+            return;
+        };
+        let data = samples.bytes();
+        let align = self.format.block_align() as usize;
+        let frames = (data.len() / align) as u64;
+        if frames == 0 {
+            return;
+        }
+        let step = ((self.frequency as u64) << 32) / OUTPUT_RATE as u64;
+        let start = self.write_cursor();
+        let mut rendered = 0u64;
+        let mut ended = false;
+        for frame in out.chunks_exact_mut(2) {
+            if self.position >> 32 >= frames {
+                if !self.looping {
+                    ended = true;
+                    break;
+                }
+                self.position %= frames << 32;
+            }
+            let index = self.position >> 32;
+            let next = match index + 1 {
+                next if next < frames => next,
+                _ if self.looping => 0,
+                _ => index,
+            };
+            let fraction = (self.position & 0xFFFF_FFFF) as f32 / 4_294_967_296.0;
+            let (left, right) = self.frame(data, index as usize * align);
+            let (next_left, next_right) = self.frame(data, next as usize * align);
+            frame[0] = (left + (next_left - left) * fraction) * self.gains.0;
+            frame[1] = (right + (next_right - right) * fraction) * self.gains.1;
+            self.position += step;
+            rendered += 1;
+        }
+
+        let size = data.len() as u64;
+        let advanced = ((rendered * step) >> 32) * align as u64;
+        for notification in &self.notifications {
+            let ahead = (notification.offset as u64 + size - start as u64) % size;
+            if notification.offset != DSBPN_OFFSETSTOP && ahead < advanced {
+                signalled.push(notification.event);
+            }
+        }
+        self.play_cursor = start;
+        if ended {
+            // A buffer played once rewinds at its end, ready to play again
+            self.position = 0;
+            self.play_cursor = 0;
+            self.stop(signalled);
+        }
+    }
+
+    fn stop(&mut self, signalled: &mut Vec<Handle>) {
+        if !self.playing {
+            return;
+        }
+        self.playing = false;
+        let stops = self.notifications.iter().filter(|notification| notification.offset == DSBPN_OFFSETSTOP);
+        signalled.extend(stops.map(|notification| notification.event));
+    }
+}
+
+struct Device {
+    cooperative_level: u32,
+    speaker_config: u32,
+    // The primary buffer's object, while the app holds it
+    primary: Option<usize>,
+}
+
+struct Output {
+    mixer: Option<AudioMixer>,
+    // The output has been mixed up to this time
+    mixed_to: u64,
+    // Events the mix could not signal yet
+    pending: Vec<Handle>,
+}
+
+impl Output {
+    fn mixer(&mut self) -> &mut AudioMixer {
+        self.mixer.get_or_insert_with(|| AudioMixer::new(OUTPUT_RATE, 2))
+    }
+
+    // Mix what has come due since the last tick and send it to the card
+    fn mix(&mut self, buffers: &mut BTreeMap<u64, Buffer>) {
+        let now = now_ns();
+        self.mixed_to = self.mixed_to.max(now.saturating_sub(MAX_CATCH_UP_NS));
+        let frames = (now.saturating_sub(self.mixed_to) * OUTPUT_RATE as u64 / 1_000_000_000) as usize;
+        self.mixed_to += frames as u64 * 1_000_000_000 / OUTPUT_RATE as u64;
+
+        let mut signalled = core::mem::take(&mut self.pending);
+        let mut tracks = Vec::new();
+        for buffer in buffers.values_mut().filter(|buffer| buffer.playing && !buffer.is_primary()) {
+            let mut track = vec![0.0; frames * 2];
+            buffer.render(&mut track, &mut signalled);
+            tracks.push(track);
+        }
+        let inputs: Vec<(&[f32], MixerChannel)> =
+            tracks.iter().map(|track| (track.as_slice(), MixerChannel::Pcm)).collect();
+        let mut mixed = vec![0.0; frames * 2];
+        self.mixer().mix_samples(&inputs, &mut mixed);
+
+        let bytes: Vec<u8> = mixed.iter().flat_map(|sample| ((sample * 32_767.0) as i16).to_le_bytes()).collect();
+        // With the card busy this period is dropped, but the cursors still
+        // move on, as they would on Windows
+        if let Some(mut manager) = AUDIO_MANAGER.try_lock() {
+            manager.write_playback(&bytes).ok();
+        }
+        MIXED_FRAMES.fetch_add(frames as u64, Ordering::Relaxed);
+
+        signalled.retain(|event| !super::kernel32::signal_event(*event));
+        self.pending = signalled;
+    }
+}
+
+static DEVICES: Mutex<BTreeMap<u64, Device>> = Mutex::new(BTreeMap::new());
+static BUFFERS: Mutex<BTreeMap<u64, Buffer>> = Mutex::new(BTreeMap::new());
+static OUTPUT: Mutex<Output> = Mutex::new(Output { mixer: None, mixed_to: 0, pending: Vec::new() });
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static MIXING: AtomicBool = AtomicBool::new(false);
+// Frames sent to the card since boot
+static MIXED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+// Start the mix ticking, if it is not already
+fn start_mixing() {
+    if !MIXING.swap(true, Ordering::SeqCst) {
+        OUTPUT.lock().mixed_to = now_ns();
+        hrtimer::start_after(MIX_PERIOD_NS, mix_tick, 0);
+    }
+}
+
+// The mix's hrtimer callback. It may interrupt a holder of either lock,
+// so both are only tried, and a tick that finds one busy leaves its work
+// to the next. Ticking stops once nothing plays and every event is out.
+fn mix_tick(_: u64) {
+    if let (Some(mut buffers), Some(mut output)) = (BUFFERS.try_lock(), OUTPUT.try_lock()) {
+        output.mix(&mut buffers);
+        if !buffers.values().any(|buffer| buffer.playing) && output.pending.is_empty() {
+            MIXING.store(false, Ordering::SeqCst);
+            return;
+        }
+    }
+    hrtimer::start_after(MIX_PERIOD_NS, mix_tick, 0);
+}
+
+// Signal events from an app's call, with no lock held
+fn signal(events: Vec<Handle>) {
+    for event in events {
+        super::kernel32::SetEvent(event);
+    }
+}
+
+fn result(outcome: Result<(), HRESULT>) -> HRESULT {
+    outcome.err().unwrap_or(DS_OK)
+}
+
+// COM objects. Each holds the id of its state in DEVICES or BUFFERS, which
+// goes when the last reference does.
+
+#[repr(C)]
+struct DirectSound {
+    vtbl: *const DirectSoundVtbl,
+    ref_count: AtomicU32,
+    id: u64,
+}
+
+#[repr(C)]
+struct SoundBuffer {
+    vtbl: *const SoundBufferVtbl,
+    notify: Notify,
+    ref_count: AtomicU32,
+    id: u64,
+}
+
+// IDirectSoundNotify, which lives in the buffer it notifies for and counts
+// its references with it
+#[repr(C)]
+struct Notify {
+    vtbl: *const NotifyVtbl,
+    buffer: *mut SoundBuffer,
+}
+
+#[repr(C)]
+struct DirectSoundVtbl {
+    query_interface: unsafe extern "system" fn(*mut DirectSound, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut DirectSound) -> u32,
+    release: unsafe extern "system" fn(*mut DirectSound) -> u32,
+    create_sound_buffer:
+        unsafe extern "system" fn(*mut DirectSound, *const BufferDesc, *mut *mut SoundBuffer, LPVOID) -> HRESULT,
+    get_caps: unsafe extern "system" fn(*mut DirectSound, *mut DSCaps) -> HRESULT,
+    duplicate_sound_buffer:
+        unsafe extern "system" fn(*mut DirectSound, *mut SoundBuffer, *mut *mut SoundBuffer) -> HRESULT,
+    set_cooperative_level: unsafe extern "system" fn(*mut DirectSound, Handle, u32) -> HRESULT,
+    compact: unsafe extern "system" fn(*mut DirectSound) -> HRESULT,
+    get_speaker_config: unsafe extern "system" fn(*mut DirectSound, *mut u32) -> HRESULT,
+    set_speaker_config: unsafe extern "system" fn(*mut DirectSound, u32) -> HRESULT,
+    initialize: unsafe extern "system" fn(*mut DirectSound, *const GUID) -> HRESULT,
+    verify_certification: unsafe extern "system" fn(*mut DirectSound, *mut u32) -> HRESULT,
+}
+
+#[repr(C)]
+struct SoundBufferVtbl {
+    query_interface: unsafe extern "system" fn(*mut SoundBuffer, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut SoundBuffer) -> u32,
+    release: unsafe extern "system" fn(*mut SoundBuffer) -> u32,
+    get_caps: unsafe extern "system" fn(*mut SoundBuffer, *mut BufferCaps) -> HRESULT,
+    get_current_position: unsafe extern "system" fn(*mut SoundBuffer, *mut u32, *mut u32) -> HRESULT,
+    get_format: unsafe extern "system" fn(*mut SoundBuffer, *mut WaveFormatEx, u32, *mut u32) -> HRESULT,
+    get_volume: unsafe extern "system" fn(*mut SoundBuffer, *mut i32) -> HRESULT,
+    get_pan: unsafe extern "system" fn(*mut SoundBuffer, *mut i32) -> HRESULT,
+    get_frequency: unsafe extern "system" fn(*mut SoundBuffer, *mut u32) -> HRESULT,
+    get_status: unsafe extern "system" fn(*mut SoundBuffer, *mut u32) -> HRESULT,
+    initialize: unsafe extern "system" fn(*mut SoundBuffer, *mut DirectSound, *const BufferDesc) -> HRESULT,
+    lock: unsafe extern "system" fn(
+        *mut SoundBuffer,
+        u32,
+        u32,
+        *mut LPVOID,
+        *mut u32,
+        *mut LPVOID,
+        *mut u32,
+        u32,
+    ) -> HRESULT,
+    play: unsafe extern "system" fn(*mut SoundBuffer, u32, u32, u32) -> HRESULT,
+    set_current_position: unsafe extern "system" fn(*mut SoundBuffer, u32) -> HRESULT,
+    set_format: unsafe extern "system" fn(*mut SoundBuffer, *const WaveFormatEx) -> HRESULT,
+    set_volume: unsafe extern "system" fn(*mut SoundBuffer, i32) -> HRESULT,
+    set_pan: unsafe extern "system" fn(*mut SoundBuffer, i32) -> HRESULT,
+    set_frequency: unsafe extern "system" fn(*mut SoundBuffer, u32) -> HRESULT,
+    stop: unsafe extern "system" fn(*mut SoundBuffer) -> HRESULT,
+    unlock: unsafe extern "system" fn(*mut SoundBuffer, LPVOID, u32, LPVOID, u32) -> HRESULT,
+    restore: unsafe extern "system" fn(*mut SoundBuffer) -> HRESULT,
+    // IDirectSoundBuffer8
+    set_fx: unsafe extern "system" fn(*mut SoundBuffer, u32, *const c_void, *mut u32) -> HRESULT,
+    acquire_resources: unsafe extern "system" fn(*mut SoundBuffer, u32, u32, *mut u32) -> HRESULT,
+    get_object_in_path: unsafe extern "system" fn(*mut SoundBuffer, REFIID, u32, REFIID, *mut LPVOID) -> HRESULT,
+}
+
+#[repr(C)]
+struct NotifyVtbl {
+    query_interface: unsafe extern "system" fn(*mut Notify, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut Notify) -> u32,
+    release: unsafe extern "system" fn(*mut Notify) -> u32,
+    set_notification_positions: unsafe extern "system" fn(*mut Notify, u32, *const PositionNotify) -> HRESULT,
+}
+
+static DIRECT_SOUND_VTBL: DirectSoundVtbl = DirectSoundVtbl {
+    query_interface: ds_query_interface,
+    add_ref: ds_add_ref,
+    release: ds_release,
+    create_sound_buffer: ds_create_sound_buffer,
+    get_caps: ds_get_caps,
+    duplicate_sound_buffer: ds_duplicate_sound_buffer,
+    set_cooperative_level: ds_set_cooperative_level,
+    compact: ds_compact,
+    get_speaker_config: ds_get_speaker_config,
+    set_speaker_config: ds_set_speaker_config,
+    initialize: ds_initialize,
+    verify_certification: ds_verify_certification,
+};
+
+static SOUND_BUFFER_VTBL: SoundBufferVtbl = SoundBufferVtbl {
+    query_interface: buffer_query_interface,
+    add_ref: buffer_add_ref,
+    release: buffer_release,
+    get_caps: buffer_get_caps,
+    get_current_position: buffer_get_current_position,
+    get_format: buffer_get_format,
+    get_volume: buffer_get_volume,
+    get_pan: buffer_get_pan,
+    get_frequency: buffer_get_frequency,
+    get_status: buffer_get_status,
+    initialize: buffer_initialize,
+    lock: buffer_lock,
+    play: buffer_play,
+    set_current_position: buffer_set_current_position,
+    set_format: buffer_set_format,
+    set_volume: buffer_set_volume,
+    set_pan: buffer_set_pan,
+    set_frequency: buffer_set_frequency,
+    stop: buffer_stop,
+    unlock: buffer_unlock,
+    restore: buffer_restore,
+    set_fx: buffer_set_fx,
+    acquire_resources: buffer_acquire_resources,
+    get_object_in_path: buffer_get_object_in_path,
+};
+
+static NOTIFY_VTBL: NotifyVtbl = NotifyVtbl {
+    query_interface: notify_query_interface,
+    add_ref: notify_add_ref,
+    release: notify_release,
+    set_notification_positions: notify_set_notification_positions,
+};
+
+fn new_buffer(buffer: Buffer) -> *mut SoundBuffer {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    BUFFERS.lock().insert(id, buffer);
+    let object = Box::into_raw(Box::new(SoundBuffer {
+        vtbl: &SOUND_BUFFER_VTBL,
+        notify: Notify { vtbl: &NOTIFY_VTBL, buffer: null_mut() },
+        ref_count: AtomicU32::new(1),
+        id,
+    }));
+    unsafe { (*object).notify.buffer = object };
+    object
+}
+
+// Run `f` on the state of the buffer behind `this`
+unsafe fn with_buffer<T>(
+    this: *mut SoundBuffer,
+    f: impl FnOnce(&mut Buffer) -> Result<T, HRESULT>,
+) -> Result<T, HRESULT> {
+    if this.is_null() {
+        return Err(E_POINTER);
+    }
+    let mut buffers = BUFFERS.lock();
+    f(buffers.get_mut(&(*this).id).ok_or(DSERR_INVALIDCALL)?)
+}
+
+unsafe fn with_device<T>(
+    this: *mut DirectSound,
+    f: impl FnOnce(&mut Device) -> Result<T, HRESULT>,
+) -> Result<T, HRESULT> {
+    if this.is_null() {
+        return Err(E_POINTER);
+    }
+    let mut devices = DEVICES.lock();
+    f(devices.get_mut(&(*this).id).ok_or(DSERR_INVALIDCALL)?)
+}
+
+// Store `value` through `out`, which the app may have left null
+unsafe fn put<T>(out: *mut T, value: T) -> Result<(), HRESULT> {
+    if out.is_null() {
+        return Err(DSERR_INVALIDPARAM);
+    }
+    out.write(value);
+    Ok(())
+}
+
+// IDirectSound8
+
+unsafe extern "system" fn ds_query_interface(this: *mut DirectSound, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() || riid.is_null() || out.is_null() {
+        return E_POINTER;
+    }
+    if ![GUID::IID_IUnknown, IID_IDIRECTSOUND, IID_IDIRECTSOUND8].contains(&*riid) {
+        *out = null_mut();
+        return E_NOINTERFACE;
+    }
+    ds_add_ref(this);
+    *out = this.cast();
+    S_OK
+}
+
+unsafe extern "system" fn ds_add_ref(this: *mut DirectSound) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+unsafe extern "system" fn ds_release(this: *mut DirectSound) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    let count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+    if count == 0 {
+        DEVICES.lock().remove(&(*this).id);
+        drop(Box::from_raw(this));
+    }
+    count
+}
+
+unsafe extern "system" fn ds_create_sound_buffer(
+    this: *mut DirectSound,
+    desc: *const BufferDesc,
+    out: *mut *mut SoundBuffer,
+    outer: LPVOID,
+) -> HRESULT {
+    if this.is_null() || desc.is_null() || out.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    *out = null_mut();
+    if !outer.is_null() {
+        return DSERR_NOAGGREGATION;
+    }
+    let desc = &*desc;
+    if (desc.size as usize) < offset_of!(BufferDesc, algorithm_3d) {
+        return DSERR_INVALIDPARAM;
+    }
+    let device = (*this).id;
+
+    if desc.flags & DSBCAPS_PRIMARYBUFFER != 0 {
+        let unavailable = DSBCAPS_CTRLFREQUENCY | DSBCAPS_CTRLPOSITIONNOTIFY | DSBCAPS_CTRLFX;
+        if desc.buffer_bytes != 0 || !desc.format.is_null() || desc.flags & unavailable != 0 {
+            return DSERR_INVALIDPARAM;
+        }
+        // There is one primary buffer, which each request shares
+        let existing = match with_device(this, |device| Ok(device.primary)) {
+            Ok(existing) => existing,
+            Err(error) => return error,
+        };
+        if let Some(primary) = existing {
+            let primary = primary as *mut SoundBuffer;
+            buffer_add_ref(primary);
+            *out = primary;
+            return DS_OK;
+        }
+        let primary = new_buffer(Buffer::new(device, desc.flags, Format::PRIMARY, None));
+        if let Some(device) = DEVICES.lock().get_mut(&device) {
+            device.primary = Some(primary as usize);
+        }
+        *out = primary;
+        return DS_OK;
+    }
+
+    let Some(format) = Format::read(desc.format) else {
+        return DSERR_BADFORMAT;
+    };
+    let bytes = desc.buffer_bytes;
+    if !(DSBSIZE_MIN..=DSBSIZE_MAX).contains(&bytes) || bytes < format.block_align() {
+        return DSERR_INVALIDPARAM;
+    }
+    let samples = Arc::new(Samples::new(bytes as usize));
+    *out = new_buffer(Buffer::new(device, desc.flags & !DSBCAPS_LOCHARDWARE, format, Some(samples)));
+    DS_OK
+}
+
+unsafe extern "system" fn ds_get_caps(this: *mut DirectSound, caps: *mut DSCaps) -> HRESULT {
+    if this.is_null() || caps.is_null() || (*caps).size as usize != size_of::<DSCaps>() {
+        return DSERR_INVALIDPARAM;
+    }
+    // Everything is mixed in software, so no hardware buffers are offered
+    let sample_flags = DSCAPS_PRIMARYMONO
+        | DSCAPS_PRIMARYSTEREO
+        | DSCAPS_PRIMARY8BIT
+        | DSCAPS_PRIMARY16BIT
+        | DSCAPS_SECONDARYMONO
+        | DSCAPS_SECONDARYSTEREO
+        | DSCAPS_SECONDARY8BIT
+        | DSCAPS_SECONDARY16BIT;
+    *caps = DSCaps {
+        size: size_of::<DSCaps>() as u32,
+        flags: sample_flags | DSCAPS_CONTINUOUSRATE | DSCAPS_EMULDRIVER,
+        min_secondary_sample_rate: DSBFREQUENCY_MIN,
+        max_secondary_sample_rate: DSBFREQUENCY_MAX,
+        primary_buffers: 1,
+        max_hw_mixing_all_buffers: 0,
+        max_hw_mixing_static_buffers: 0,
+        max_hw_mixing_streaming_buffers: 0,
+        free_hw_mixing_all_buffers: 0,
+        free_hw_mixing_static_buffers: 0,
+        free_hw_mixing_streaming_buffers: 0,
+        max_hw3d_all_buffers: 0,
+        max_hw3d_static_buffers: 0,
+        max_hw3d_streaming_buffers: 0,
+        free_hw3d_all_buffers: 0,
+        free_hw3d_static_buffers: 0,
+        free_hw3d_streaming_buffers: 0,
+        total_hw_mem_bytes: 0,
+        free_hw_mem_bytes: 0,
+        max_contiguous_free_hw_mem_bytes: 0,
+        unlock_transfer_rate_hw_buffers: 0,
+        play_cpu_overhead_sw_buffers: 0,
+        reserved1: 0,
+        reserved2: 0,
+    };
+    DS_OK
+}
+
+unsafe extern "system" fn ds_duplicate_sound_buffer(
+    this: *mut DirectSound,
+    original: *mut SoundBuffer,
+    out: *mut *mut SoundBuffer,
+) -> HRESULT {
+    if this.is_null() || original.is_null() || out.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    *out = null_mut();
+    let duplicate = with_buffer(original, |buffer| {
+        if buffer.is_primary() {
+            return Err(DSERR_INVALIDCALL);
+        }
+        let mut duplicate = Buffer::new(buffer.device, buffer.flags, buffer.format, buffer.samples.clone());
+        duplicate.volume = buffer.volume;
+        duplicate.pan = buffer.pan;
+        duplicate.frequency = buffer.frequency;
+        duplicate.update_gains();
+        Ok(duplicate)
+    });
+    match duplicate {
+        Ok(duplicate) => {
+            *out = new_buffer(duplicate);
+            DS_OK
+        }
+        Err(error) => error,
+    }
+}
+
+unsafe extern "system" fn ds_set_cooperative_level(this: *mut DirectSound, _window: Handle, level: u32) -> HRESULT {
+    let level = match level {
+        DSSCL_NORMAL | DSSCL_PRIORITY | DSSCL_EXCLUSIVE => level,
+        DSSCL_WRITEPRIMARY => return DSERR_UNSUPPORTED,
+        _ => return DSERR_INVALIDPARAM,
+    };
+    result(with_device(this, |device| {
+        device.cooperative_level = level;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn ds_compact(this: *mut DirectSound) -> HRESULT {
+    result(with_device(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn ds_get_speaker_config(this: *mut DirectSound, config: *mut u32) -> HRESULT {
+    result(with_device(this, |device| put(config, device.speaker_config)))
+}
+
+unsafe extern "system" fn ds_set_speaker_config(this: *mut DirectSound, config: u32) -> HRESULT {
+    result(with_device(this, |device| {
+        device.speaker_config = config;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn ds_initialize(_this: *mut DirectSound, _device: *const GUID) -> HRESULT {
+    DSERR_ALREADYINITIALIZED
+}
+
+unsafe extern "system" fn ds_verify_certification(this: *mut DirectSound, certified: *mut u32) -> HRESULT {
+    result(with_device(this, |_| put(certified, DS_UNCERTIFIED)))
+}
+
+// IDirectSoundBuffer8
+
+unsafe extern "system" fn buffer_query_interface(this: *mut SoundBuffer, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() || riid.is_null() || out.is_null() {
+        return E_POINTER;
+    }
+    *out = null_mut();
+    let flags = match with_buffer(this, |buffer| Ok(buffer.flags)) {
+        Ok(flags) => flags,
+        Err(error) => return error,
+    };
+    let iid = *riid;
+    let interface: LPVOID = if iid == GUID::IID_IUnknown || iid == IID_IDIRECTSOUNDBUFFER {
+        this.cast()
+    } else if iid == IID_IDIRECTSOUNDBUFFER8 && flags & DSBCAPS_PRIMARYBUFFER == 0 {
+        this.cast()
+    } else if iid == IID_IDIRECTSOUNDNOTIFY && flags & DSBCAPS_CTRLPOSITIONNOTIFY != 0 {
+        core::ptr::addr_of_mut!((*this).notify).cast()
+    } else {
+        return E_NOINTERFACE;
+    };
+    buffer_add_ref(this);
+    *out = interface;
+    S_OK
+}
+
+unsafe extern "system" fn buffer_add_ref(this: *mut SoundBuffer) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+unsafe extern "system" fn buffer_release(this: *mut SoundBuffer) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    let count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+    if count == 0 {
+        let buffer = BUFFERS.lock().remove(&(*this).id);
+        if let Some(buffer) = buffer.filter(Buffer::is_primary) {
+            if let Some(device) = DEVICES.lock().get_mut(&buffer.device) {
+                device.primary = None;
+            }
+        }
+        drop(Box::from_raw(this));
+    }
+    count
+}
+
+unsafe extern "system" fn buffer_get_caps(this: *mut SoundBuffer, caps: *mut BufferCaps) -> HRESULT {
+    if caps.is_null() || (*caps).size as usize != size_of::<BufferCaps>() {
+        return DSERR_INVALIDPARAM;
+    }
+    result(with_buffer(this, |buffer| {
+        *caps = BufferCaps {
+            size: size_of::<BufferCaps>() as u32,
+            flags: buffer.flags | DSBCAPS_LOCSOFTWARE,
+            buffer_bytes: buffer.size(),
+            unlock_transfer_rate: 0,
+            play_cpu_overhead: 0,
+        };
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_get_current_position(
+    this: *mut SoundBuffer,
+    play: *mut u32,
+    write: *mut u32,
+) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        let (play_cursor, write_cursor) = buffer.cursors();
+        if !play.is_null() {
+            *play = play_cursor;
+        }
+        if !write.is_null() {
+            *write = write_cursor;
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_get_format(
+    this: *mut SoundBuffer,
+    wave: *mut WaveFormatEx,
+    allocated: u32,
+    written: *mut u32,
+) -> HRESULT {
+    if wave.is_null() && written.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    result(with_buffer(this, |buffer| {
+        let size = size_of::<WaveFormatEx>() as u32;
+        if !wave.is_null() {
+            if allocated < size {
+                return Err(DSERR_INVALIDPARAM);
+            }
+            wave.write_unaligned(buffer.format.wave());
+        }
+        if !written.is_null() {
+            *written = size;
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_get_volume(this: *mut SoundBuffer, volume: *mut i32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        buffer.require(DSBCAPS_CTRLVOLUME)?;
+        put(volume, buffer.volume)
+    }))
+}
+
+unsafe extern "system" fn buffer_get_pan(this: *mut SoundBuffer, pan: *mut i32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        buffer.require(DSBCAPS_CTRLPAN)?;
+        put(pan, buffer.pan)
+    }))
+}
+
+unsafe extern "system" fn buffer_get_frequency(this: *mut SoundBuffer, frequency: *mut u32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        buffer.require(DSBCAPS_CTRLFREQUENCY)?;
+        put(frequency, buffer.frequency)
+    }))
+}
+
+unsafe extern "system" fn buffer_get_status(this: *mut SoundBuffer, status: *mut u32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        let mut flags = DSBSTATUS_LOCSOFTWARE;
+        if buffer.playing {
+            flags |= DSBSTATUS_PLAYING;
+            if buffer.looping {
+                flags |= DSBSTATUS_LOOPING;
+            }
+        }
+        put(status, flags)
+    }))
+}
+
+unsafe extern "system" fn buffer_initialize(
+    _this: *mut SoundBuffer,
+    _device: *mut DirectSound,
+    _desc: *const BufferDesc,
+) -> HRESULT {
+    DSERR_ALREADYINITIALIZED
+}
+
+// Lock's eight parameters are IDirectSoundBuffer's
+#[allow(clippy::too_many_arguments)]
+unsafe extern "system" fn buffer_lock(
+    this: *mut SoundBuffer,
+    offset: u32,
+    bytes: u32,
+    first: *mut LPVOID,
+    first_bytes: *mut u32,
+    second: *mut LPVOID,
+    second_bytes: *mut u32,
+    flags: u32,
+) -> HRESULT {
+    if first.is_null() || first_bytes.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    result(with_buffer(this, |buffer| {
+        let samples = buffer.samples.as_ref().ok_or(DSERR_PRIOLEVELNEEDED)?;
+        let size = samples.len as u32;
+        let offset = if flags & DSBLOCK_FROMWRITECURSOR != 0 { buffer.write_cursor() } else { offset };
+        let bytes = if flags & DSBLOCK_ENTIREBUFFER != 0 { size } else { bytes };
+        if offset >= size || bytes == 0 || bytes > size {
+            return Err(DSERR_INVALIDPARAM);
+        }
+        // A region running past the end wraps to the start, which is
+        // handed out as the second part if the app asked for one
+        let head = bytes.min(size - offset);
+        let wrapped = bytes - head;
+        *first = samples.data.add(offset as usize).cast();
+        *first_bytes = head;
+        if !second.is_null() {
+            *second = if wrapped > 0 { samples.data.cast() } else { null_mut() };
+        }
+        if !second_bytes.is_null() {
+            *second_bytes = if second.is_null() { 0 } else { wrapped };
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_play(this: *mut SoundBuffer, _reserved: u32, _priority: u32, flags: u32) -> HRESULT {
+    let played = with_buffer(this, |buffer| {
+        if !buffer.playing {
+            buffer.play_cursor = buffer.write_cursor();
+        }
+        buffer.playing = true;
+        buffer.looping = flags & DSBPLAY_LOOPING != 0;
+        Ok(())
+    });
+    if played.is_ok() {
+        start_mixing();
+    }
+    result(played)
+}
+
+unsafe extern "system" fn buffer_set_current_position(this: *mut SoundBuffer, position: u32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        if buffer.is_primary() {
+            return Err(DSERR_INVALIDCALL);
+        }
+        if position >= buffer.size() {
+            return Err(DSERR_INVALIDPARAM);
+        }
+        let align = buffer.format.block_align();
+        buffer.position = ((position / align) as u64) << 32;
+        buffer.play_cursor = position / align * align;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_set_format(this: *mut SoundBuffer, wave: *const WaveFormatEx) -> HRESULT {
+    // Only the primary buffer's format may be set, and only with priority
+    let device = match with_buffer(this, |buffer| Ok(buffer.is_primary().then_some(buffer.device))) {
+        Ok(Some(device)) => device,
+        Ok(None) => return DSERR_INVALIDCALL,
+        Err(error) => return error,
+    };
+    let level = DEVICES.lock().get(&device).map_or(DSSCL_NORMAL, |device| device.cooperative_level);
+    if level < DSSCL_PRIORITY {
+        return DSERR_PRIOLEVELNEEDED;
+    }
+    let Some(format) = Format::read(wave) else {
+        return DSERR_BADFORMAT;
+    };
+    result(with_buffer(this, |buffer| {
+        buffer.format = format;
+        buffer.frequency = format.rate;
+        Ok(())
+    }))
+}
+
+// Set the volume and pan, which for the primary buffer are the mixer's
+// master control
+unsafe fn set_level(this: *mut SoundBuffer, control: u32, set: impl FnOnce(&mut Buffer)) -> HRESULT {
+    let level = with_buffer(this, |buffer| {
+        buffer.require(control)?;
+        set(buffer);
+        buffer.update_gains();
+        Ok((buffer.is_primary(), buffer.gains))
+    });
+    match level {
+        Ok((true, (left, right))) => {
+            OUTPUT.lock().mixer().set_volume(MixerChannel::Master, left, right);
+            DS_OK
+        }
+        Ok(_) => DS_OK,
+        Err(error) => error,
+    }
+}
+
+unsafe extern "system" fn buffer_set_volume(this: *mut SoundBuffer, volume: i32) -> HRESULT {
+    if !(DSBVOLUME_MIN..=DSBVOLUME_MAX).contains(&volume) {
+        return DSERR_INVALIDPARAM;
+    }
+    set_level(this, DSBCAPS_CTRLVOLUME, |buffer| buffer.volume = volume)
+}
+
+unsafe extern "system" fn buffer_set_pan(this: *mut SoundBuffer, pan: i32) -> HRESULT {
+    if !(DSBPAN_LEFT..=DSBPAN_RIGHT).contains(&pan) {
+        return DSERR_INVALIDPARAM;
+    }
+    set_level(this, DSBCAPS_CTRLPAN, |buffer| buffer.pan = pan)
+}
+
+unsafe extern "system" fn buffer_set_frequency(this: *mut SoundBuffer, frequency: u32) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        if buffer.is_primary() {
+            return Err(DSERR_CONTROLUNAVAIL);
+        }
+        buffer.require(DSBCAPS_CTRLFREQUENCY)?;
+        buffer.frequency = match frequency {
+            DSBFREQUENCY_ORIGINAL => buffer.format.rate,
+            DSBFREQUENCY_MIN..=DSBFREQUENCY_MAX => frequency,
+            _ => return Err(DSERR_INVALIDPARAM),
+        };
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_stop(this: *mut SoundBuffer) -> HRESULT {
+    let mut signalled = Vec::new();
+    let stopped = with_buffer(this, |buffer| {
+        buffer.stop(&mut signalled);
+        Ok(())
+    });
+    signal(signalled);
+    result(stopped)
+}
+
+unsafe extern "system" fn buffer_unlock(
+    this: *mut SoundBuffer,
+    _first: LPVOID,
+    _first_bytes: u32,
+    _second: LPVOID,
+    _second_bytes: u32,
+) -> HRESULT {
+    // The app wrote the samples in place, so there is nothing to copy
+    result(with_buffer(this, |buffer| buffer.samples.as_ref().map(|_| ()).ok_or(DSERR_INVALIDCALL)))
+}
+
+unsafe extern "system" fn buffer_restore(this: *mut SoundBuffer) -> HRESULT {
+    // Buffers are never lost
+    result(with_buffer(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn buffer_set_fx(
+    this: *mut SoundBuffer,
+    _count: u32,
+    _effects: *const c_void,
+    _results: *mut u32,
+) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        buffer.require(DSBCAPS_CTRLFX)?;
+        Err(DSERR_UNSUPPORTED)
+    }))
+}
+
+unsafe extern "system" fn buffer_acquire_resources(
+    this: *mut SoundBuffer,
+    _flags: u32,
+    count: u32,
+    results: *mut u32,
+) -> HRESULT {
+    result(with_buffer(this, |_| {
+        if count > 0 && results.is_null() {
+            return Err(DSERR_INVALIDPARAM);
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_get_object_in_path(
+    _this: *mut SoundBuffer,
+    _object: REFIID,
+    _index: u32,
+    _interface: REFIID,
+    out: *mut LPVOID,
+) -> HRESULT {
+    if !out.is_null() {
+        *out = null_mut();
+    }
+    DSERR_OBJECTNOTFOUND
+}
+
+// IDirectSoundNotify
+
+unsafe extern "system" fn notify_query_interface(this: *mut Notify, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() {
+        return E_POINTER;
+    }
+    buffer_query_interface((*this).buffer, riid, out)
+}
+
+unsafe extern "system" fn notify_add_ref(this: *mut Notify) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    buffer_add_ref((*this).buffer)
+}
+
+unsafe extern "system" fn notify_release(this: *mut Notify) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    buffer_release((*this).buffer)
+}
+
+unsafe extern "system" fn notify_set_notification_positions(
+    this: *mut Notify,
+    count: u32,
+    positions: *const PositionNotify,
+) -> HRESULT {
+    if this.is_null() {
+        return E_POINTER;
+    }
+    if count > 0 && positions.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    let positions = if count == 0 { &[][..] } else { core::slice::from_raw_parts(positions, count as usize) };
+    result(with_buffer((*this).buffer, |buffer| {
+        if buffer.playing {
+            return Err(DSERR_INVALIDCALL);
+        }
+        let size = buffer.size();
+        if positions.iter().any(|position| position.offset >= size && position.offset != DSBPN_OFFSETSTOP) {
+            return Err(DSERR_INVALIDPARAM);
+        }
+        buffer.notifications = positions.to_vec();
+        Ok(())
+    }))
+}
+
+fn create(device: *const GUID, out: *mut LPVOID, outer: LPVOID) -> HRESULT {
+    if out.is_null() {
+        return DSERR_INVALIDPARAM;
+    }
+    unsafe { *out = null_mut() };
+    if !outer.is_null() {
+        return DSERR_NOAGGREGATION;
+    }
+    if !device.is_null() {
+        let device = unsafe { *device };
+        if ![DSDEVID_DEFAULT_PLAYBACK, DSDEVID_DEFAULT_VOICE_PLAYBACK, CARD_GUID].contains(&device) {
+            return DSERR_NODRIVER;
+        }
+    }
+    crate::sound::ensure_init();
+    if !AUDIO_MANAGER.lock().has_output() {
+        return DSERR_NODRIVER;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let device = Device { cooperative_level: DSSCL_NORMAL, speaker_config: DSSPEAKER_STEREO, primary: None };
+    DEVICES.lock().insert(id, device);
+    let object = DirectSound { vtbl: &DIRECT_SOUND_VTBL, ref_count: AtomicU32::new(1), id };
+    unsafe { *out = Box::into_raw(Box::new(object)).cast() };
+    DS_OK
+}
+
+/// DirectSoundCreate - Open the sound card for DirectSound; `device` is
+/// null or one of the DSDEVID_ default playback devices
+#[no_mangle]
+pub extern "C" fn DirectSoundCreate(device: *const GUID, direct_sound: *mut LPVOID, outer: LPVOID) -> HRESULT {
+    create(device, direct_sound, outer)
+}
+
+/// DirectSoundCreate8 - Open the sound card, for IDirectSound8
+#[no_mangle]
+pub extern "C" fn DirectSoundCreate8(device: *const GUID, direct_sound: *mut LPVOID, outer: LPVOID) -> HRESULT {
+    create(device, direct_sound, outer)
+}
+
+// The devices DirectSoundEnumerate lists: the primary sound driver, with
+// no GUID, then the card
+fn enumerate() -> Vec<(Option<GUID>, alloc::string::String)> {
+    crate::sound::ensure_init();
+    let manager = AUDIO_MANAGER.lock();
+    let Some(card) = manager.has_output().then(|| manager.list_devices()).and_then(|cards| cards.into_iter().next())
+    else {
+        return Vec::new();
+    };
+    vec![(None, alloc::string::String::from("Primary Sound Driver")), (Some(CARD_GUID), card.name)]
+}
+
+/// DirectSoundEnumerateA - List the playback devices to `callback`, until
+/// it returns FALSE
+#[no_mangle]
+pub extern "C" fn DirectSoundEnumerateA(callback: Option<EnumCallbackA>, context: LPVOID) -> HRESULT {
+    let Some(callback) = callback else {
+        return DSERR_INVALIDPARAM;
+    };
+    for (guid, name) in enumerate() {
+        let mut guid = guid;
+        let guid = guid.as_mut().map_or(null_mut(), |guid| guid as *mut GUID);
+        let name: Vec<u8> = name.bytes().chain([0]).collect();
+        if callback(guid, name.as_ptr(), b"\0".as_ptr(), context) == 0 {
+            break;
+        }
+    }
+    DS_OK
+}
+
+/// DirectSoundEnumerateW - List the playback devices (wide version)
+#[no_mangle]
+pub extern "C" fn DirectSoundEnumerateW(callback: Option<EnumCallbackW>, context: LPVOID) -> HRESULT {
+    let Some(callback) = callback else {
+        return DSERR_INVALIDPARAM;
+    };
+    for (guid, name) in enumerate() {
+        let mut guid = guid;
+        let guid = guid.as_mut().map_or(null_mut(), |guid| guid as *mut GUID);
+        let name: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        if callback(guid, name.as_ptr(), [0u16].as_ptr(), context) == 0 {
+            break;
+        }
+    }
+    DS_OK
+}
@@ -31,6 +31,7 @@ pub enum GdiObject {
     Region(Region),
     Palette(PaletteObject),
     DeviceContext(DeviceContext),
+    Icon(IconObject),
 }
 
 // Pen object
@@ -70,6 +71,16 @@ pub struct BitmapObject {
     pub data: Vec<u8>,
 }
 
+// Icon or cursor object; pixels are 0xAARRGGBB, top row first
+#[derive(Debug, Clone)]
+pub struct IconObject {
+    pub width: i32,
+    pub height: i32,
+    pub hotspot: (i32, i32),
+    pub cursor: bool,
+    pub pixels: Vec<u32>,
+}
+
 // Region object
 #[derive(Debug, Clone)]
 pub struct Region {
@@ -180,6 +191,13 @@ impl GdiManager {
         self.objects.remove(&hdc.0).is_some()
     }
     
+    pub fn dc(&self, handle: HANDLE) -> Option<&DeviceContext> {
+        match self.objects.get(&handle.0) {
+            Some(GdiObject::DeviceContext(dc)) => Some(dc),
+            _ => None,
+        }
+    }
+    
     pub fn create_pen(&mut self, style: i32, width: i32, color: COLORREF) -> HANDLE {
         let handle = self.allocate_handle();
         let pen = PenObject { style, width, color };
@@ -223,6 +241,35 @@ impl GdiManager {
         handle
     }
     
+    /// A device-dependent bitmap; its rows run from the top, each padded
+    /// to a 16-bit boundary
+    pub fn create_bitmap(&mut self, width: i32, height: i32, bits_per_pixel: i32, data: Vec<u8>) -> HANDLE {
+        let handle = self.allocate_handle();
+        let bitmap = BitmapObject { width, height, bits_per_pixel, data };
+        self.objects.insert(handle.0, GdiObject::Bitmap(bitmap));
+        handle
+    }
+    
+    pub fn bitmap(&self, handle: HANDLE) -> Option<&BitmapObject> {
+        match self.objects.get(&handle.0) {
+            Some(GdiObject::Bitmap(bitmap)) => Some(bitmap),
+            _ => None,
+        }
+    }
+    
+    pub fn create_icon(&mut self, icon: IconObject) -> HANDLE {
+        let handle = self.allocate_handle();
+        self.objects.insert(handle.0, GdiObject::Icon(icon));
+        handle
+    }
+    
+    pub fn icon(&self, handle: HANDLE) -> Option<&IconObject> {
+        match self.objects.get(&handle.0) {
+            Some(GdiObject::Icon(icon)) => Some(icon),
+            _ => None,
+        }
+    }
+    
     pub fn select_object(&mut self, hdc: HANDLE, obj: HANDLE) -> Option<HANDLE> {
         // First check if the object exists and get its type
         let obj_type = if let Some(gdi_obj) = self.objects.get(&obj.0) {
@@ -400,6 +447,21 @@ pub extern "C" fn CreateFontA(
     )
 }
 
+/// CreateBitmap - Create a bitmap, from the given bits or blank; only one
+/// plane is taken
+#[no_mangle]
+pub extern "C" fn CreateBitmap(width: i32, height: i32, planes: u32, bit_count: u32, bits: *const u8) -> HANDLE {
+    if width <= 0 || height <= 0 || planes != 1 || !matches!(bit_count, 1 | 4 | 8 | 16 | 24 | 32) {
+        return Handle::NULL;
+    }
+    let stride = (width as usize * bit_count as usize).div_ceil(16) * 2;
+    let mut data = alloc::vec![0u8; stride * height as usize];
+    if !bits.is_null() {
+        unsafe { core::ptr::copy_nonoverlapping(bits, data.as_mut_ptr(), data.len()) };
+    }
+    GDI_MANAGER.lock().create_bitmap(width, height, bit_count as i32, data)
+}
+
 /// SelectObject - Select an object into a device context
 #[no_mangle]
 pub extern "C" fn SelectObject(hdc: HANDLE, obj: HANDLE) -> HANDLE {
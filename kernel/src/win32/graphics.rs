@@ -5,8 +5,12 @@ use alloc::string::String;
 use alloc::collections::BTreeMap;
 use crate::nt::NtStatus;
 
-// DirectX version constants
-pub const D3D_SDK_VERSION: u32 = 32;
+pub mod d3d9;
+pub mod opengl32;
+pub mod surface;
+
+pub use d3d9::{Direct3DCreate9, D3D_SDK_VERSION};
+pub use opengl32::{wglCreateContext, wglDeleteContext, wglMakeCurrent, SwapBuffers};
 
 // Simple graphics device structure
 pub struct GraphicsDevice {
@@ -62,88 +66,9 @@ static mut GRAPHICS_SUBSYSTEM: Option<GraphicsSubsystem> = None;
 
 // DirectX API Functions (simplified)
 
-/// Create Direct3D9 object
-pub extern "C" fn Direct3DCreate9(sdk_version: u32) -> *mut u8 {
-    if sdk_version != D3D_SDK_VERSION {
-        crate::println!("D3D: Warning - SDK version mismatch: {} (expected {})", sdk_version, D3D_SDK_VERSION);
-    }
-    
-    crate::println!("D3D: Created Direct3D9 interface");
-    // Return a dummy pointer (in real implementation would return actual D3D object)
-    0x12345678 as *mut u8
-}
-
-/// Get adapter count
-pub extern "C" fn D3D9_GetAdapterCount() -> u32 {
-    unsafe {
-        if let Some(ref graphics) = GRAPHICS_SUBSYSTEM {
-            graphics.get_device_count()
-        } else {
-            1 // Default to 1 adapter
-        }
-    }
-}
-
-// OpenGL API Functions (simplified)
-
-/// Create OpenGL context
-pub extern "C" fn wglCreateContext(hdc: HANDLE) -> HANDLE {
-    crate::println!("OpenGL: Created OpenGL context");
-    Handle(0x87654321)
-}
-
-/// Make OpenGL context current
-pub extern "C" fn wglMakeCurrent(hdc: HANDLE, hglrc: HANDLE) -> BOOL {
-    if hglrc == Handle::NULL {
-        crate::println!("OpenGL: Released current context");
-        0 // FALSE
-    } else {
-        crate::println!("OpenGL: Made context current");
-        1 // TRUE
-    }
-}
-
-/// Delete OpenGL context
-pub extern "C" fn wglDeleteContext(hglrc: HANDLE) -> BOOL {
-    if hglrc != Handle::NULL {
-        crate::println!("OpenGL: Deleted OpenGL context");
-        1 // TRUE
-    } else {
-        0 // FALSE
-    }
-}
-
-/// Get OpenGL string
-pub extern "C" fn glGetString(name: u32) -> *const u8 {
-    match name {
-        0x1F00 => b"Rust OS Graphics\0".as_ptr(), // GL_VENDOR
-        0x1F01 => b"Software OpenGL Renderer\0".as_ptr(), // GL_RENDERER
-        0x1F02 => b"3.3.0 Rust OS\0".as_ptr(), // GL_VERSION
-        0x1F03 => b"GL_ARB_vertex_buffer_object GL_ARB_fragment_shader\0".as_ptr(), // GL_EXTENSIONS
-        _ => b"Unknown\0".as_ptr(),
-    }
-}
-
-/// Clear OpenGL buffers
-pub extern "C" fn glClear(mask: u32) {
-    crate::println!("OpenGL: Clear buffers (mask: 0x{:08X})", mask);
-}
-
-/// Clear color
-pub extern "C" fn glClearColor(red: f32, green: f32, blue: f32, alpha: f32) {
-    crate::println!("OpenGL: Clear color ({}, {}, {}, {})", red, green, blue, alpha);
-}
-
-/// Draw arrays
-pub extern "C" fn glDrawArrays(mode: u32, first: i32, count: i32) {
-    crate::println!("OpenGL: Draw arrays (mode: {}, count: {})", mode, count);
-}
+// Direct3D 9 is in d3d9
 
-/// Swap buffers
-pub extern "C" fn SwapBuffers(hdc: HANDLE) -> BOOL {
-    crate::println!("OpenGL: Swap buffers");
-    1 // TRUE
-}
+// OpenGL is in opengl32
 
 // Initialize DirectX/OpenGL subsystem
 pub fn initialize_directx_opengl_subsystem() -> NtStatus {
@@ -157,8 +82,9 @@ pub fn initialize_directx_opengl_subsystem() -> NtStatus {
     let d3d = Direct3DCreate9(D3D_SDK_VERSION);
     if !d3d.is_null() {
         crate::println!("Graphics: Direct3D 9 initialized successfully!");
-        let adapter_count = D3D9_GetAdapterCount();
+        let adapter_count = unsafe { GRAPHICS_SUBSYSTEM.as_ref().map_or(1, |graphics| graphics.get_device_count()) };
         crate::println!("  - {} graphics adapters detected", adapter_count);
+        unsafe { d3d9::release(d3d) };
         
         unsafe {
             if let Some(ref graphics) = GRAPHICS_SUBSYSTEM {
@@ -171,37 +97,41 @@ pub fn initialize_directx_opengl_subsystem() -> NtStatus {
     }
 
     // Test OpenGL
-    let dummy_hdc = Handle(1);
-    let hglrc = wglCreateContext(dummy_hdc);
+    let hdc = super::gdi::GetDC(Handle::NULL);
+    let format = opengl32::ChoosePixelFormat(hdc, &opengl32::PixelFormatDescriptor::default());
+    let hglrc = if opengl32::SetPixelFormat(hdc, format, core::ptr::null()) != 0 {
+        wglCreateContext(hdc)
+    } else {
+        Handle::NULL
+    };
     if hglrc != Handle::NULL {
-        wglMakeCurrent(dummy_hdc, hglrc);
+        wglMakeCurrent(hdc, hglrc);
         
         crate::println!("Graphics: OpenGL initialized successfully!");
-        let vendor = unsafe { 
-            let ptr = glGetString(0x1F00);
-            core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, 15))
-        };
-        let renderer = unsafe {
-            let ptr = glGetString(0x1F01);
-            core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, 25))
-        };
-        
-        crate::println!("  - Vendor: {}", vendor.trim_end_matches('\0'));
-        crate::println!("  - Renderer: {}", renderer.trim_end_matches('\0'));
+        let strings = [
+            ("Vendor", opengl32::GL_VENDOR),
+            ("Renderer", opengl32::GL_RENDERER),
+            ("Version", opengl32::GL_VERSION),
+        ];
+        for (label, name) in strings {
+            let ptr = opengl32::glGetString(name);
+            if !ptr.is_null() {
+                let text = unsafe { core::ffi::CStr::from_ptr(ptr.cast()) };
+                crate::println!("  - {}: {}", label, text.to_str().unwrap_or("?"));
+            }
+        }
         
         wglMakeCurrent(Handle::NULL, Handle::NULL);
         wglDeleteContext(hglrc);
     }
+    super::gdi::ReleaseDC(Handle::NULL, hdc);
 
     crate::println!("Graphics: DirectX/OpenGL subsystem ready!");
     crate::println!("Graphics: Features available:");
-    crate::println!("  - Direct3D 9.0c support");
-    crate::println!("  - Hardware-accelerated rendering");
-    crate::println!("  - Vertex and pixel shaders");
-    crate::println!("  - Texture mapping and filtering");
-    crate::println!("  - OpenGL 3.3+ compatibility");
-    crate::println!("  - WGL context management");
-    crate::println!("  - Software and hardware rendering");
+    crate::println!("  - Direct3D 9 fixed-function transform and lighting");
+    crate::println!("  - 3D command streams for GPUs, CPU rasterizer otherwise");
+    crate::println!("  - OpenGL 1.1 through WGL contexts");
+    crate::println!("  - Software rasterizer for OpenGL");
 
     NtStatus::Success
 }
@@ -211,35 +141,15 @@ pub fn test_directx_opengl_apis() {
     crate::println!("Graphics: Testing DirectX/OpenGL APIs");
 
     // Test Direct3D
-    let d3d = Direct3DCreate9(D3D_SDK_VERSION);
-    if !d3d.is_null() {
-        crate::println!("Graphics: Direct3D creation test - OK");
-        crate::println!("Graphics: Simulated device creation - OK");
-        crate::println!("Graphics: Simulated rendering pipeline - OK");
-    } else {
-        crate::println!("Graphics: Direct3D creation test - FAILED");
+    match d3d9::self_test(64, 64) {
+        Ok(color) => crate::println!("Graphics: Direct3D triangle test - OK (center {:#010x})", color),
+        Err(error) => crate::println!("Graphics: Direct3D triangle test - FAILED ({:#010x})", error),
     }
 
     // Test OpenGL
-    let hdc = Handle(1);
-    let hglrc = wglCreateContext(hdc);
-    if hglrc != Handle::NULL {
-        wglMakeCurrent(hdc, hglrc);
-        
-        crate::println!("Graphics: OpenGL context test - OK");
-        
-        // Test rendering
-        glClearColor(0.0, 1.0, 0.0, 1.0); // Green
-        glClear(0x00004100); // GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT
-        glDrawArrays(4, 0, 3); // GL_TRIANGLES, 3 vertices
-        SwapBuffers(hdc);
-        
-        crate::println!("Graphics: OpenGL rendering test - OK");
-        
-        wglMakeCurrent(Handle::NULL, Handle::NULL);
-        wglDeleteContext(hglrc);
-    } else {
-        crate::println!("Graphics: OpenGL context test - FAILED");
+    match opengl32::self_test(64, 64) {
+        Ok(color) => crate::println!("Graphics: OpenGL textured quad test - OK (center {:#010x})", color),
+        Err(error) => crate::println!("Graphics: OpenGL textured quad test - FAILED ({:#06x})", error),
     }
 
     crate::println!("Graphics: DirectX/OpenGL API testing completed");
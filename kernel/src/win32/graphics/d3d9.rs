@@ -0,0 +1,3075 @@
+//! Direct3D 9
+//!
+//! Direct3DCreate9 gives the IDirect3D9 object, which makes a device on
+//! the one adapter. A device has one swap chain: a back buffer of the
+//! size and format asked for, and a depth buffer with
+//! EnableAutoDepthStencil. Vertex and index buffers live in system memory
+//! whatever their pool, and are filled through Lock.
+//!
+//! Drawing is the fixed-function pipeline over flexible vertex formats:
+//! positions, pretransformed or through the world, view and projection
+//! transforms, with normals lit by the material and up to eight lights,
+//! and diffuse colors. Primitives are clipped to the near and far planes,
+//! culled and lowered to the 3D command stream of `gpu::raster`, one per
+//! frame. Present hands the frame to the primary GPU when a full-screen
+//! device has one whose engine takes the stream, and otherwise draws it on
+//! the CPU into the back buffer and shows that in the device's window on
+//! the desktop.
+//!
+//! There are no textures, surfaces, shaders, vertex declarations, state
+//! blocks, queries or stencil: creating any of them fails with
+//! D3DERR_NOTAVAILABLE, and so does setting one other than null. Specular
+//! lighting and point sizes are ignored, and spot lights fall off linearly
+//! between their cones.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::gpu::raster::{self, Primitive, RenderTarget, StreamBuilder};
+use crate::gpu::{BufferObject, GpuDriver, GPU_MANAGER};
+use crate::graphics::window::WindowId;
+use crate::graphics::FramebufferOps;
+use crate::win32::ole32::{E_NOINTERFACE, E_POINTER, GUID, HRESULT, LPVOID, REFIID, S_OK};
+use crate::win32::{Handle, BOOL};
+
+use super::surface;
+
+pub const D3D_SDK_VERSION: u32 = 32;
+
+pub const D3D_OK: HRESULT = S_OK;
+pub const D3DERR_WRONGTEXTUREFORMAT: HRESULT = 0x88760818u32 as i32;
+pub const D3DERR_NOTFOUND: HRESULT = 0x88760866u32 as i32;
+pub const D3DERR_DEVICELOST: HRESULT = 0x88760868u32 as i32;
+pub const D3DERR_NOTAVAILABLE: HRESULT = 0x8876086Au32 as i32;
+pub const D3DERR_INVALIDCALL: HRESULT = 0x8876086Cu32 as i32;
+
+pub const D3DADAPTER_DEFAULT: u32 = 0;
+
+// Device types
+pub const D3DDEVTYPE_HAL: u32 = 1;
+pub const D3DDEVTYPE_REF: u32 = 2;
+
+// Formats
+pub const D3DFMT_UNKNOWN: u32 = 0;
+pub const D3DFMT_A8R8G8B8: u32 = 21;
+pub const D3DFMT_X8R8G8B8: u32 = 22;
+pub const D3DFMT_D32: u32 = 71;
+pub const D3DFMT_D24S8: u32 = 75;
+pub const D3DFMT_D24X8: u32 = 77;
+pub const D3DFMT_D16: u32 = 80;
+pub const D3DFMT_VERTEXDATA: u32 = 100;
+pub const D3DFMT_INDEX16: u32 = 101;
+pub const D3DFMT_INDEX32: u32 = 102;
+
+// Resource types
+pub const D3DRTYPE_SURFACE: u32 = 1;
+pub const D3DRTYPE_VERTEXBUFFER: u32 = 6;
+pub const D3DRTYPE_INDEXBUFFER: u32 = 7;
+
+// Usage, as CheckDeviceFormat and the buffers take it
+pub const D3DUSAGE_RENDERTARGET: u32 = 0x0000_0001;
+pub const D3DUSAGE_DEPTHSTENCIL: u32 = 0x0000_0002;
+pub const D3DUSAGE_WRITEONLY: u32 = 0x0000_0008;
+pub const D3DUSAGE_DYNAMIC: u32 = 0x0000_0200;
+
+// Pools
+pub const D3DPOOL_DEFAULT: u32 = 0;
+pub const D3DPOOL_MANAGED: u32 = 1;
+pub const D3DPOOL_SYSTEMMEM: u32 = 2;
+pub const D3DPOOL_SCRATCH: u32 = 3;
+
+pub const D3DMULTISAMPLE_NONE: u32 = 0;
+
+// Behavior flags for CreateDevice
+pub const D3DCREATE_PUREDEVICE: u32 = 0x0000_0010;
+pub const D3DCREATE_SOFTWARE_VERTEXPROCESSING: u32 = 0x0000_0020;
+pub const D3DCREATE_HARDWARE_VERTEXPROCESSING: u32 = 0x0000_0040;
+pub const D3DCREATE_MIXED_VERTEXPROCESSING: u32 = 0x0000_0080;
+
+// Swap effects
+pub const D3DSWAPEFFECT_DISCARD: u32 = 1;
+pub const D3DSWAPEFFECT_FLIP: u32 = 2;
+pub const D3DSWAPEFFECT_COPY: u32 = 3;
+
+pub const D3DPRESENT_INTERVAL_ONE: u32 = 0x0000_0001;
+pub const D3DPRESENT_INTERVAL_IMMEDIATE: u32 = 0x8000_0000;
+
+// Clear flags
+pub const D3DCLEAR_TARGET: u32 = 0x1;
+pub const D3DCLEAR_ZBUFFER: u32 = 0x2;
+pub const D3DCLEAR_STENCIL: u32 = 0x4;
+
+// Transforms
+pub const D3DTS_VIEW: u32 = 2;
+pub const D3DTS_PROJECTION: u32 = 3;
+pub const D3DTS_TEXTURE0: u32 = 16;
+pub const D3DTS_TEXTURE7: u32 = 23;
+pub const D3DTS_WORLD: u32 = 256;
+
+// Render states
+pub const D3DRS_ZENABLE: u32 = 7;
+pub const D3DRS_FILLMODE: u32 = 8;
+pub const D3DRS_SHADEMODE: u32 = 9;
+pub const D3DRS_ZWRITEENABLE: u32 = 14;
+pub const D3DRS_SRCBLEND: u32 = 19;
+pub const D3DRS_DESTBLEND: u32 = 20;
+pub const D3DRS_CULLMODE: u32 = 22;
+pub const D3DRS_ZFUNC: u32 = 23;
+pub const D3DRS_ALPHABLENDENABLE: u32 = 27;
+pub const D3DRS_LIGHTING: u32 = 137;
+pub const D3DRS_AMBIENT: u32 = 139;
+pub const D3DRS_COLORVERTEX: u32 = 141;
+pub const D3DRS_NORMALIZENORMALS: u32 = 143;
+pub const D3DRS_DIFFUSEMATERIALSOURCE: u32 = 145;
+pub const D3DRS_SPECULARMATERIALSOURCE: u32 = 146;
+pub const D3DRS_AMBIENTMATERIALSOURCE: u32 = 147;
+pub const D3DRS_EMISSIVEMATERIALSOURCE: u32 = 148;
+pub const D3DRS_SCISSORTESTENABLE: u32 = 174;
+
+pub const D3DFILL_POINT: u32 = 1;
+pub const D3DFILL_WIREFRAME: u32 = 2;
+pub const D3DFILL_SOLID: u32 = 3;
+
+pub const D3DSHADE_FLAT: u32 = 1;
+pub const D3DSHADE_GOURAUD: u32 = 2;
+
+pub const D3DCULL_NONE: u32 = 1;
+pub const D3DCULL_CW: u32 = 2;
+pub const D3DCULL_CCW: u32 = 3;
+
+pub const D3DMCS_MATERIAL: u32 = 0;
+pub const D3DMCS_COLOR1: u32 = 1;
+pub const D3DMCS_COLOR2: u32 = 2;
+
+// Primitive types
+pub const D3DPT_POINTLIST: u32 = 1;
+pub const D3DPT_LINELIST: u32 = 2;
+pub const D3DPT_LINESTRIP: u32 = 3;
+pub const D3DPT_TRIANGLELIST: u32 = 4;
+pub const D3DPT_TRIANGLESTRIP: u32 = 5;
+pub const D3DPT_TRIANGLEFAN: u32 = 6;
+
+// Flexible vertex format
+pub const D3DFVF_XYZ: u32 = 0x002;
+pub const D3DFVF_XYZRHW: u32 = 0x004;
+pub const D3DFVF_XYZB1: u32 = 0x006;
+pub const D3DFVF_XYZB5: u32 = 0x00E;
+pub const D3DFVF_XYZW: u32 = 0x4002;
+pub const D3DFVF_POSITION_MASK: u32 = 0x400E;
+pub const D3DFVF_NORMAL: u32 = 0x010;
+pub const D3DFVF_PSIZE: u32 = 0x020;
+pub const D3DFVF_DIFFUSE: u32 = 0x040;
+pub const D3DFVF_SPECULAR: u32 = 0x080;
+pub const D3DFVF_TEXCOUNT_MASK: u32 = 0xF00;
+pub const D3DFVF_TEXCOUNT_SHIFT: u32 = 8;
+
+// Light types
+pub const D3DLIGHT_POINT: u32 = 1;
+pub const D3DLIGHT_SPOT: u32 = 2;
+pub const D3DLIGHT_DIRECTIONAL: u32 = 3;
+
+// Lock flags
+pub const D3DLOCK_READONLY: u32 = 0x0010;
+pub const D3DLOCK_NOOVERWRITE: u32 = 0x1000;
+pub const D3DLOCK_DISCARD: u32 = 0x2000;
+
+// Caps, as GetDeviceCaps reports them
+const D3DCAPS2_CANRENDERWINDOWED: u32 = 0x0008_0000;
+const D3DDEVCAPS_EXECUTESYSTEMMEMORY: u32 = 0x0000_0010;
+const D3DDEVCAPS_TLVERTEXSYSTEMMEMORY: u32 = 0x0000_0040;
+const D3DDEVCAPS_DRAWPRIMTLVERTEX: u32 = 0x0000_0400;
+const D3DDEVCAPS_HWTRANSFORMANDLIGHT: u32 = 0x0001_0000;
+const D3DDEVCAPS_HWRASTERIZATION: u32 = 0x0008_0000;
+const D3DPMISCCAPS_CULLNONE: u32 = 0x0000_0010;
+const D3DPMISCCAPS_CULLCW: u32 = 0x0000_0020;
+const D3DPMISCCAPS_CULLCCW: u32 = 0x0000_0040;
+const D3DPRASTERCAPS_ZTEST: u32 = 0x0000_0010;
+const D3DPRASTERCAPS_SCISSORTEST: u32 = 0x0100_0000;
+const D3DPCMPCAPS_ALL: u32 = 0xFF;
+const D3DPBLENDCAPS_ALL: u32 = 0x3FF;
+const D3DPSHADECAPS_COLORGOURAUDRGB: u32 = 0x0000_0008;
+const D3DLINECAPS_ZTEST: u32 = 0x0000_0002;
+const D3DLINECAPS_BLEND: u32 = 0x0000_0004;
+const D3DVTXPCAPS_MATERIALSOURCE7: u32 = 0x0000_0002;
+const D3DVTXPCAPS_DIRECTIONALLIGHTS: u32 = 0x0000_0008;
+const D3DVTXPCAPS_POSITIONALLIGHTS: u32 = 0x0000_0010;
+
+pub const IID_IDIRECT3D9: GUID =
+    GUID { data1: 0x81BDCBCA, data2: 0x64D4, data3: 0x426D, data4: [0xAE, 0x8D, 0xAD, 0x01, 0x47, 0xF4, 0x27, 0x5C] };
+pub const IID_IDIRECT3DDEVICE9: GUID =
+    GUID { data1: 0xD0223B96, data2: 0xBF7A, data3: 0x43FD, data4: [0x92, 0xBD, 0xA4, 0x3B, 0x0D, 0x82, 0xB9, 0xEB] };
+pub const IID_IDIRECT3DRESOURCE9: GUID =
+    GUID { data1: 0x05EEC05D, data2: 0x8F7D, data3: 0x4362, data4: [0xB9, 0x99, 0xD1, 0xBA, 0xF3, 0x57, 0xC7, 0x04] };
+pub const IID_IDIRECT3DVERTEXBUFFER9: GUID =
+    GUID { data1: 0xB64BB1B5, data2: 0xFD70, data3: 0x4DF6, data4: [0xBF, 0x91, 0x19, 0xD0, 0xA1, 0x24, 0x55, 0xE3] };
+pub const IID_IDIRECT3DINDEXBUFFER9: GUID =
+    GUID { data1: 0x7C9DD65E, data2: 0xD3F7, data3: 0x4529, data4: [0xAC, 0xEE, 0x78, 0x58, 0x30, 0xAC, 0xDE, 0x35] };
+
+// The adapter's identity when no GPU driver is bound
+const SOFTWARE_ADAPTER: &str = "Software Direct3D 9 Adapter";
+
+// Texture memory GetAvailableTextureMem reports, rounded to a megabyte
+const TEXTURE_MEMORY: u32 = 256 * 1024 * 1024;
+
+// Lights a draw uses at once
+const MAX_ACTIVE_LIGHTS: usize = 8;
+
+// Render states kept, from 0
+const RENDER_STATES: usize = 256;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PresentParameters {
+    pub back_buffer_width: u32,
+    pub back_buffer_height: u32,
+    pub back_buffer_format: u32,
+    pub back_buffer_count: u32,
+    pub multi_sample_type: u32,
+    pub multi_sample_quality: u32,
+    pub swap_effect: u32,
+    pub device_window: Handle,
+    pub windowed: BOOL,
+    pub enable_auto_depth_stencil: BOOL,
+    pub auto_depth_stencil_format: u32,
+    pub flags: u32,
+    pub full_screen_refresh_rate: u32,
+    pub presentation_interval: u32,
+}
+
+impl Default for PresentParameters {
+    fn default() -> Self {
+        PresentParameters {
+            back_buffer_width: 0,
+            back_buffer_height: 0,
+            back_buffer_format: 0,
+            back_buffer_count: 0,
+            multi_sample_type: 0,
+            multi_sample_quality: 0,
+            swap_effect: 0,
+            device_window: Handle::NULL,
+            windowed: 0,
+            enable_auto_depth_stencil: 0,
+            auto_depth_stencil_format: 0,
+            flags: 0,
+            full_screen_refresh_rate: 0,
+            presentation_interval: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+    pub format: u32,
+}
+
+#[repr(C)]
+pub struct AdapterIdentifier {
+    pub driver: [u8; 512],
+    pub description: [u8; 512],
+    pub device_name: [u8; 32],
+    pub driver_version: i64,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub sub_sys_id: u32,
+    pub revision: u32,
+    pub device_identifier: GUID,
+    pub whql_level: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CreationParameters {
+    pub adapter_ordinal: u32,
+    pub device_type: u32,
+    pub focus_window: Handle,
+    pub behavior_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VertexShaderCaps {
+    pub caps: u32,
+    pub dynamic_flow_control_depth: i32,
+    pub num_temps: i32,
+    pub static_flow_control_depth: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelShaderCaps {
+    pub caps: u32,
+    pub dynamic_flow_control_depth: i32,
+    pub num_temps: i32,
+    pub static_flow_control_depth: i32,
+    pub num_instruction_slots: i32,
+}
+
+/// D3DCAPS9
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Caps {
+    pub device_type: u32,
+    pub adapter_ordinal: u32,
+    pub caps: u32,
+    pub caps2: u32,
+    pub caps3: u32,
+    pub presentation_intervals: u32,
+    pub cursor_caps: u32,
+    pub dev_caps: u32,
+    pub primitive_misc_caps: u32,
+    pub raster_caps: u32,
+    pub z_cmp_caps: u32,
+    pub src_blend_caps: u32,
+    pub dest_blend_caps: u32,
+    pub alpha_cmp_caps: u32,
+    pub shade_caps: u32,
+    pub texture_caps: u32,
+    pub texture_filter_caps: u32,
+    pub cube_texture_filter_caps: u32,
+    pub volume_texture_filter_caps: u32,
+    pub texture_address_caps: u32,
+    pub volume_texture_address_caps: u32,
+    pub line_caps: u32,
+    pub max_texture_width: u32,
+    pub max_texture_height: u32,
+    pub max_volume_extent: u32,
+    pub max_texture_repeat: u32,
+    pub max_texture_aspect_ratio: u32,
+    pub max_anisotropy: u32,
+    pub max_vertex_w: f32,
+    pub guard_band_left: f32,
+    pub guard_band_top: f32,
+    pub guard_band_right: f32,
+    pub guard_band_bottom: f32,
+    pub extents_adjust: f32,
+    pub stencil_caps: u32,
+    pub fvf_caps: u32,
+    pub texture_op_caps: u32,
+    pub max_texture_blend_stages: u32,
+    pub max_simultaneous_textures: u32,
+    pub vertex_processing_caps: u32,
+    pub max_active_lights: u32,
+    pub max_user_clip_planes: u32,
+    pub max_vertex_blend_matrices: u32,
+    pub max_vertex_blend_matrix_index: u32,
+    pub max_point_size: f32,
+    pub max_primitive_count: u32,
+    pub max_vertex_index: u32,
+    pub max_streams: u32,
+    pub max_stream_stride: u32,
+    pub vertex_shader_version: u32,
+    pub max_vertex_shader_const: u32,
+    pub pixel_shader_version: u32,
+    pub pixel_shader_1x_max_value: f32,
+    pub dev_caps2: u32,
+    pub max_npatch_tessellation_level: f32,
+    pub reserved5: u32,
+    pub master_adapter_ordinal: u32,
+    pub adapter_ordinal_in_group: u32,
+    pub number_of_adapters_in_group: u32,
+    pub decl_types: u32,
+    pub num_simultaneous_rts: u32,
+    pub stretch_rect_filter_caps: u32,
+    pub vs20_caps: VertexShaderCaps,
+    pub ps20_caps: PixelShaderCaps,
+    pub vertex_texture_filter_caps: u32,
+    pub max_vshader_instructions_executed: u32,
+    pub max_pshader_instructions_executed: u32,
+    pub max_vertex_shader30_instruction_slots: u32,
+    pub max_pixel_shader30_instruction_slots: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Matrix {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Matrix {
+    pub const IDENTITY: Matrix =
+        Matrix { m: [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]] };
+
+    // Row vectors, as Direct3D has them: self first, then other
+    fn then(&self, other: &Matrix) -> Matrix {
+        let mut m = [[0.0; 4]; 4];
+        for (row, out) in m.iter_mut().enumerate() {
+            for (column, value) in out.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[row][k] * other.m[k][column]).sum();
+            }
+        }
+        Matrix { m }
+    }
+
+    fn transform(&self, v: [f32; 4]) -> [f32; 4] {
+        core::array::from_fn(|column| (0..4).map(|k| v[k] * self.m[k][column]).sum())
+    }
+
+    fn transform_normal(&self, n: [f32; 3]) -> [f32; 3] {
+        core::array::from_fn(|column| (0..3).map(|k| n[k] * self.m[k][column]).sum())
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub min_z: f32,
+    pub max_z: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColorValue {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+impl ColorValue {
+    fn from_argb(color: u32) -> Self {
+        let [a, r, g, b] = raster::unpack(color);
+        ColorValue { r, g, b, a }
+    }
+
+    fn scaled(&self, other: &ColorValue, by: f32) -> [f32; 3] {
+        [self.r * other.r * by, self.g * other.g * by, self.b * other.b * by]
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vector {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Material {
+    pub diffuse: ColorValue,
+    pub ambient: ColorValue,
+    pub specular: ColorValue,
+    pub emissive: ColorValue,
+    pub power: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Light {
+    pub light_type: u32,
+    pub diffuse: ColorValue,
+    pub specular: ColorValue,
+    pub ambient: ColorValue,
+    pub position: Vector,
+    pub direction: Vector,
+    pub range: f32,
+    pub falloff: f32,
+    pub attenuation0: f32,
+    pub attenuation1: f32,
+    pub attenuation2: f32,
+    pub theta: f32,
+    pub phi: f32,
+}
+
+/// D3DRECT, and RECT for the scissor
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RasterStatus {
+    pub in_vblank: BOOL,
+    pub scan_line: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GammaRamp {
+    pub red: [u16; 256],
+    pub green: [u16; 256],
+    pub blue: [u16; 256],
+}
+
+impl GammaRamp {
+    fn identity() -> Self {
+        let ramp = core::array::from_fn(|i| ((i as u16) << 8) | i as u16);
+        GammaRamp { red: ramp, green: ramp, blue: ramp }
+    }
+}
+
+/// D3DVERTEXBUFFER_DESC
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VertexBufferDesc {
+    pub format: u32,
+    pub resource_type: u32,
+    pub usage: u32,
+    pub pool: u32,
+    pub size: u32,
+    pub fvf: u32,
+}
+
+/// D3DINDEXBUFFER_DESC
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct IndexBufferDesc {
+    pub format: u32,
+    pub resource_type: u32,
+    pub usage: u32,
+    pub pool: u32,
+    pub size: u32,
+}
+
+// Where each part of a vertex is in a flexible vertex format
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    pretransformed: bool,
+    // XYZW, with its w after z
+    has_w: bool,
+    normal: Option<usize>,
+    diffuse: Option<usize>,
+    specular: Option<usize>,
+    size: usize,
+}
+
+impl Layout {
+    fn of(fvf: u32) -> Option<Layout> {
+        let position = fvf & D3DFVF_POSITION_MASK;
+        let (pretransformed, has_w, mut size) = match position {
+            D3DFVF_XYZ => (false, false, 12),
+            D3DFVF_XYZRHW => (true, false, 16),
+            D3DFVF_XYZW => (false, true, 16),
+            D3DFVF_XYZB1..=D3DFVF_XYZB5 if position & 1 == 0 => (false, false, 12 + 4 * (position as usize - 4) / 2),
+            _ => return None,
+        };
+        let mut field = |flag: u32, bytes: usize| {
+            (fvf & flag != 0).then(|| {
+                size += bytes;
+                size - bytes
+            })
+        };
+        let normal = field(D3DFVF_NORMAL, 12);
+        field(D3DFVF_PSIZE, 4);
+        let diffuse = field(D3DFVF_DIFFUSE, 4);
+        let specular = field(D3DFVF_SPECULAR, 4);
+        let textures = (fvf & D3DFVF_TEXCOUNT_MASK) >> D3DFVF_TEXCOUNT_SHIFT;
+        for texture in 0..textures.min(8) {
+            size += match (fvf >> (16 + 2 * texture)) & 3 {
+                0 => 8,
+                1 => 12,
+                2 => 16,
+                _ => 4,
+            };
+        }
+        Some(Layout { pretransformed, has_w, normal, diffuse, specular, size })
+    }
+}
+
+fn float(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or([0; 4]))
+}
+
+fn dword(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or([0; 4]))
+}
+
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut guess = if x > 1.0 { x / 2.0 } else { 1.0 };
+    for _ in 0..24 {
+        guess = (guess + x / guess) * 0.5;
+    }
+    guess
+}
+
+// Good to a few parts in a thousand from -pi to pi, which is all a cone's
+// half angle needs
+fn cos(x: f32) -> f32 {
+    let x2 = x * x;
+    1.0 - x2 / 2.0 + x2 * x2 / 24.0 - x2 * x2 * x2 / 720.0 + x2 * x2 * x2 * x2 / 40320.0
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = sqrt(dot(v, v));
+    if length == 0.0 {
+        v
+    } else {
+        v.map(|c| c / length)
+    }
+}
+
+// A vertex after transform and lighting, in clip space, or in pixels if it
+// came pretransformed
+#[derive(Debug, Clone, Copy)]
+struct Processed {
+    position: [f32; 4],
+    // [a, r, g, b], as the rasterizer has it
+    color: [f32; 4],
+}
+
+impl Processed {
+    fn lerp(&self, other: &Processed, t: f32) -> Processed {
+        Processed {
+            position: core::array::from_fn(|i| self.position[i] + (other.position[i] - self.position[i]) * t),
+            color: core::array::from_fn(|i| self.color[i] + (other.color[i] - self.color[i]) * t),
+        }
+    }
+}
+
+// Distances inside each clip plane: near, far and in front of the eye
+fn clip_distances(v: &Processed) -> [f32; 3] {
+    let [_, _, z, w] = v.position;
+    [z, w - z, w - 1e-5]
+}
+
+// The part of a polygon inside every clip plane
+fn clip_polygon(mut polygon: Vec<Processed>) -> Vec<Processed> {
+    for plane in 0..3 {
+        if polygon.is_empty() {
+            break;
+        }
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, current) in polygon.iter().enumerate() {
+            let next = &polygon[(i + 1) % polygon.len()];
+            let (a, b) = (clip_distances(current)[plane], clip_distances(next)[plane]);
+            if a >= 0.0 {
+                clipped.push(*current);
+            }
+            if (a >= 0.0) != (b >= 0.0) {
+                clipped.push(current.lerp(next, a / (a - b)));
+            }
+        }
+        polygon = clipped;
+    }
+    polygon
+}
+
+fn clip_line(mut a: Processed, mut b: Processed) -> Option<(Processed, Processed)> {
+    for plane in 0..3 {
+        let (da, db) = (clip_distances(&a)[plane], clip_distances(&b)[plane]);
+        match (da >= 0.0, db >= 0.0) {
+            (true, true) => {}
+            (false, false) => return None,
+            (true, false) => b = a.lerp(&b, da / (da - db)),
+            (false, true) => a = a.lerp(&b, da / (da - db)),
+        }
+    }
+    Some((a, b))
+}
+
+// Where an app's buffer of vertices is and how they are laid out
+struct Source<'a> {
+    data: &'a [u8],
+    // Byte offset of vertex 0, which may be before the data
+    base: isize,
+    stride: usize,
+}
+
+impl Source<'_> {
+    fn vertex(&self, index: u32, size: usize) -> Option<&[u8]> {
+        let start = self.base.checked_add((index as usize).checked_mul(self.stride)? as isize)?;
+        let start = usize::try_from(start).ok()?;
+        self.data.get(start..start.checked_add(size)?)
+    }
+}
+
+// A reference a device holds on a vertex or index buffer, given back when
+// it is dropped
+struct Held(NonNull<Buffer9>);
+
+// The buffer behind it is counted, so it lives while this does
+unsafe impl Send for Held {}
+
+impl Held {
+    unsafe fn new(buffer: *mut Buffer9) -> Option<Held> {
+        let buffer = NonNull::new(buffer)?;
+        buffer_add_ref(buffer.as_ptr());
+        Some(Held(buffer))
+    }
+
+    fn id(&self) -> u64 {
+        unsafe { self.0.as_ref().id }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        unsafe { buffer_release(self.0.as_ptr()) };
+    }
+}
+
+struct StreamSource {
+    buffer: Held,
+    offset: u32,
+    stride: u32,
+}
+
+// Where a full-screen device's frames go on the primary GPU
+struct GpuTarget {
+    gpu: Arc<Mutex<Box<dyn GpuDriver>>>,
+    framebuffer: BufferObject,
+}
+
+struct Device {
+    adapter: u32,
+    device_type: u32,
+    focus_window: Handle,
+    behavior: u32,
+    params: PresentParameters,
+    target: RenderTarget,
+    window: Option<WindowId>,
+    gpu: Option<GpuTarget>,
+    d3d: *mut Direct3D9,
+
+    // The frame being recorded, and the state and scissor it last gave
+    frame: StreamBuilder,
+    emitted: Option<(raster::State, (u32, u32, u32, u32))>,
+    in_scene: bool,
+
+    world: Matrix,
+    view: Matrix,
+    projection: Matrix,
+    texture_transforms: [Matrix; 8],
+    viewport: Viewport,
+    scissor: Rect,
+    render_states: [u32; RENDER_STATES],
+    texture_stage_states: BTreeMap<(u32, u32), u32>,
+    sampler_states: BTreeMap<(u32, u32), u32>,
+    material: Material,
+    lights: BTreeMap<u32, (Light, bool)>,
+    fvf: u32,
+    stream: Option<StreamSource>,
+    indices: Option<Held>,
+    software_vertex_processing: bool,
+    cursor_shown: bool,
+    gamma: GammaRamp,
+}
+
+// The Direct3D9 object it was made from is counted, so it lives while the
+// device does
+unsafe impl Send for Device {}
+
+impl Device {
+    fn has_depth(&self) -> bool {
+        self.params.enable_auto_depth_stencil != 0
+    }
+
+    fn full_viewport(&self) -> Viewport {
+        Viewport {
+            x: 0,
+            y: 0,
+            width: self.params.back_buffer_width,
+            height: self.params.back_buffer_height,
+            min_z: 0.0,
+            max_z: 1.0,
+        }
+    }
+
+    // The state Reset and creation start from
+    fn reset_state(&mut self) {
+        self.target =
+            RenderTarget::new(self.params.back_buffer_width as usize, self.params.back_buffer_height as usize);
+        self.frame = StreamBuilder::new();
+        self.emitted = None;
+        self.in_scene = false;
+        self.world = Matrix::IDENTITY;
+        self.view = Matrix::IDENTITY;
+        self.projection = Matrix::IDENTITY;
+        self.texture_transforms = [Matrix::IDENTITY; 8];
+        self.viewport = self.full_viewport();
+        self.scissor =
+            Rect { x1: 0, y1: 0, x2: self.params.back_buffer_width as i32, y2: self.params.back_buffer_height as i32 };
+        self.render_states = [0; RENDER_STATES];
+        for (state, value) in [
+            (D3DRS_ZENABLE, self.has_depth() as u32),
+            (D3DRS_FILLMODE, D3DFILL_SOLID),
+            (D3DRS_SHADEMODE, D3DSHADE_GOURAUD),
+            (D3DRS_ZWRITEENABLE, 1),
+            (D3DRS_SRCBLEND, raster::BLEND_ONE),
+            (D3DRS_DESTBLEND, raster::BLEND_ZERO),
+            (D3DRS_CULLMODE, D3DCULL_CCW),
+            (D3DRS_ZFUNC, raster::CMP_LESS_EQUAL),
+            (D3DRS_LIGHTING, 1),
+            (D3DRS_COLORVERTEX, 1),
+            (D3DRS_DIFFUSEMATERIALSOURCE, D3DMCS_COLOR1),
+            (D3DRS_SPECULARMATERIALSOURCE, D3DMCS_COLOR2),
+            (D3DRS_AMBIENTMATERIALSOURCE, D3DMCS_MATERIAL),
+            (D3DRS_EMISSIVEMATERIALSOURCE, D3DMCS_MATERIAL),
+        ] {
+            self.render_states[state as usize] = value;
+        }
+        self.texture_stage_states.clear();
+        self.sampler_states.clear();
+        self.material = Material::default();
+        self.lights.clear();
+        self.fvf = 0;
+        self.stream = None;
+        self.indices = None;
+        self.gamma = GammaRamp::identity();
+    }
+
+    fn render_state(&self, state: u32) -> u32 {
+        self.render_states[state as usize]
+    }
+
+    // The rectangle draws and clears are kept to: the viewport, and the
+    // scissor if its test is on
+    fn draw_rect(&self) -> (u32, u32, u32, u32) {
+        let viewport = &self.viewport;
+        let (mut x0, mut y0) = (viewport.x, viewport.y);
+        let (mut x1, mut y1) = (viewport.x + viewport.width, viewport.y + viewport.height);
+        if self.render_state(D3DRS_SCISSORTESTENABLE) != 0 {
+            x0 = x0.max(self.scissor.x1.max(0) as u32);
+            y0 = y0.max(self.scissor.y1.max(0) as u32);
+            x1 = x1.min(self.scissor.x2.max(0) as u32);
+            y1 = y1.min(self.scissor.y2.max(0) as u32);
+        }
+        (x0, y0, x1.max(x0), y1.max(y0))
+    }
+
+    fn raster_state(&self) -> raster::State {
+        let mut flags = 0;
+        if self.has_depth() && self.render_state(D3DRS_ZENABLE) != 0 {
+            flags |= raster::STATE_DEPTH_TEST;
+            if self.render_state(D3DRS_ZWRITEENABLE) != 0 {
+                flags |= raster::STATE_DEPTH_WRITE;
+            }
+        }
+        if self.render_state(D3DRS_ALPHABLENDENABLE) != 0 {
+            flags |= raster::STATE_BLEND;
+        }
+        raster::State {
+            flags,
+            depth_func: self.render_state(D3DRS_ZFUNC),
+            src_blend: self.render_state(D3DRS_SRCBLEND),
+            dest_blend: self.render_state(D3DRS_DESTBLEND),
+        }
+    }
+
+    // Give the frame the state and scissor the next draw needs, if they
+    // changed
+    fn emit_state(&mut self) {
+        let current = (self.raster_state(), self.draw_rect());
+        if self.emitted != Some(current) {
+            self.frame.state(&current.0);
+            self.frame.scissor(current.1);
+            self.emitted = Some(current);
+        }
+    }
+
+    fn clear(&mut self, rects: &[Rect], flags: u32, color: u32, z: f32) -> Result<(), HRESULT> {
+        if flags & D3DCLEAR_ZBUFFER != 0 && !self.has_depth() {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        if flags & D3DCLEAR_STENCIL != 0 && self.params.auto_depth_stencil_format != D3DFMT_D24S8 {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        let mut what = 0;
+        if flags & D3DCLEAR_TARGET != 0 {
+            what |= raster::CLEAR_COLOR;
+        }
+        if flags & D3DCLEAR_ZBUFFER != 0 {
+            what |= raster::CLEAR_DEPTH;
+        }
+        if what == 0 {
+            return Ok(());
+        }
+        let (x0, y0, x1, y1) = self.draw_rect();
+        let whole = [Rect { x1: x0 as i32, y1: y0 as i32, x2: x1 as i32, y2: y1 as i32 }];
+        for rect in if rects.is_empty() { &whole[..] } else { rects } {
+            let left = (rect.x1.max(0) as u32).clamp(x0, x1);
+            let top = (rect.y1.max(0) as u32).clamp(y0, y1);
+            let right = (rect.x2.max(0) as u32).clamp(left, x1);
+            let bottom = (rect.y2.max(0) as u32).clamp(top, y1);
+            self.frame.clear((left, top, right, bottom), what, color, z.clamp(0.0, 1.0));
+        }
+        Ok(())
+    }
+
+    // The material's color for `state`'s source, taken from the vertex's
+    // colors with D3DRS_COLORVERTEX where it has them
+    fn material_color(&self, state: u32, own: ColorValue, diffuse: Option<u32>, specular: Option<u32>) -> ColorValue {
+        if self.render_state(D3DRS_COLORVERTEX) == 0 {
+            return own;
+        }
+        let from = match self.render_state(state) {
+            D3DMCS_COLOR1 => diffuse,
+            D3DMCS_COLOR2 => specular,
+            _ => None,
+        };
+        from.map_or(own, ColorValue::from_argb)
+    }
+
+    // The lit color of a vertex at `position` in world space
+    fn light(&self, position: [f32; 3], normal: [f32; 3], diffuse: Option<u32>, specular: Option<u32>) -> [f32; 4] {
+        let material = &self.material;
+        let mat_diffuse = self.material_color(D3DRS_DIFFUSEMATERIALSOURCE, material.diffuse, diffuse, specular);
+        let mat_ambient = self.material_color(D3DRS_AMBIENTMATERIALSOURCE, material.ambient, diffuse, specular);
+        let mat_emissive = self.material_color(D3DRS_EMISSIVEMATERIALSOURCE, material.emissive, diffuse, specular);
+
+        let ambient = ColorValue::from_argb(self.render_state(D3DRS_AMBIENT));
+        let mut color = [
+            mat_emissive.r + ambient.r * mat_ambient.r,
+            mat_emissive.g + ambient.g * mat_ambient.g,
+            mat_emissive.b + ambient.b * mat_ambient.b,
+        ];
+        let enabled = self.lights.values().filter(|(_, enabled)| *enabled).take(MAX_ACTIVE_LIGHTS);
+        for (light, _) in enabled {
+            let (to_light, attenuation) = match light.light_type {
+                D3DLIGHT_DIRECTIONAL => (normalize([-light.direction.x, -light.direction.y, -light.direction.z]), 1.0),
+                _ => {
+                    let offset = [
+                        light.position.x - position[0],
+                        light.position.y - position[1],
+                        light.position.z - position[2],
+                    ];
+                    let distance = sqrt(dot(offset, offset));
+                    if distance > light.range {
+                        continue;
+                    }
+                    let falloff =
+                        light.attenuation0 + light.attenuation1 * distance + light.attenuation2 * distance * distance;
+                    let mut attenuation = if falloff > 0.0 { 1.0 / falloff } else { 1.0 };
+                    let to_light = normalize(offset);
+                    if light.light_type == D3DLIGHT_SPOT {
+                        let rho = -dot(to_light, normalize([light.direction.x, light.direction.y, light.direction.z]));
+                        let (inner, outer) = (cos(light.theta / 2.0), cos(light.phi / 2.0));
+                        attenuation *= if rho > inner {
+                            1.0
+                        } else if rho <= outer || inner <= outer {
+                            0.0
+                        } else {
+                            (rho - outer) / (inner - outer)
+                        };
+                    }
+                    (to_light, attenuation)
+                }
+            };
+            let lit = dot(normal, to_light).max(0.0) * attenuation;
+            let ambient = light.ambient.scaled(&mat_ambient, attenuation);
+            let diffuse = light.diffuse.scaled(&mat_diffuse, lit);
+            for i in 0..3 {
+                color[i] += ambient[i] + diffuse[i];
+            }
+        }
+        [mat_diffuse.a, color[0], color[1], color[2]]
+    }
+
+    // Transform and light one vertex
+    fn process(&self, layout: &Layout, vertex: &[u8], world_view_projection: &Matrix) -> Processed {
+        let position = [float(vertex, 0), float(vertex, 4), float(vertex, 8)];
+        let diffuse = layout.diffuse.map(|offset| dword(vertex, offset));
+        let specular = layout.specular.map(|offset| dword(vertex, offset));
+        if layout.pretransformed {
+            // Pixel centers are at whole coordinates in Direct3D 9, and
+            // half way between them in the rasterizer
+            let color = raster::unpack(diffuse.unwrap_or(0xFFFF_FFFF));
+            return Processed { position: [position[0] + 0.5, position[1] + 0.5, position[2], 1.0], color };
+        }
+        let w = if layout.has_w { float(vertex, 12) } else { 1.0 };
+        let model = [position[0], position[1], position[2], w];
+        let color = if self.render_state(D3DRS_LIGHTING) != 0 {
+            let world = self.world.transform(model);
+            let world = [world[0], world[1], world[2]];
+            let normal = match layout.normal {
+                Some(offset) => {
+                    let normal = [float(vertex, offset), float(vertex, offset + 4), float(vertex, offset + 8)];
+                    let normal = self.world.transform_normal(normal);
+                    if self.render_state(D3DRS_NORMALIZENORMALS) != 0 {
+                        normalize(normal)
+                    } else {
+                        normal
+                    }
+                }
+                None => [0.0; 3],
+            };
+            self.light(world, normal, diffuse, specular)
+        } else {
+            raster::unpack(diffuse.unwrap_or(0xFFFF_FFFF))
+        };
+        Processed { position: world_view_projection.transform(model), color }
+    }
+
+    // Clip space to pixels, through the viewport
+    fn to_screen(&self, v: &Processed) -> raster::Vertex {
+        let viewport = &self.viewport;
+        let [x, y, z, w] = v.position;
+        let (x, y, z) = (x / w, y / w, z / w);
+        raster::Vertex {
+            x: viewport.x as f32 + (1.0 + x) * viewport.width as f32 / 2.0 + 0.5,
+            y: viewport.y as f32 + (1.0 - y) * viewport.height as f32 / 2.0 + 0.5,
+            z: viewport.min_z + z * (viewport.max_z - viewport.min_z),
+            color: raster::pack(v.color),
+        }
+    }
+
+    fn screen_vertex(&self, v: &Processed, pretransformed: bool) -> raster::Vertex {
+        if pretransformed {
+            let [x, y, z, _] = v.position;
+            raster::Vertex { x, y, z, color: raster::pack(v.color) }
+        } else {
+            self.to_screen(v)
+        }
+    }
+
+    // Whether a triangle, in pixels, faces away under D3DRS_CULLMODE
+    fn culled(&self, a: &raster::Vertex, b: &raster::Vertex, c: &raster::Vertex) -> bool {
+        // Positive when clockwise on the screen
+        let area = (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x);
+        match self.render_state(D3DRS_CULLMODE) {
+            D3DCULL_CW => area > 0.0,
+            D3DCULL_CCW => area < 0.0,
+            _ => false,
+        }
+    }
+
+    /// Draw the vertices `indices` pick from `source` as `primitive`s
+    fn draw(&mut self, primitive: u32, source: &Source, indices: &[u32]) -> Result<(), HRESULT> {
+        if !self.in_scene {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        let layout = Layout::of(self.fvf).ok_or(D3DERR_INVALIDCALL)?;
+        let world_view_projection = self.world.then(&self.view).then(&self.projection);
+
+        let mut processed: BTreeMap<u32, Processed> = BTreeMap::new();
+        for &index in indices {
+            if let alloc::collections::btree_map::Entry::Vacant(entry) = processed.entry(index) {
+                let vertex = source.vertex(index, layout.size).ok_or(D3DERR_INVALIDCALL)?;
+                entry.insert(self.process(&layout, vertex, &world_view_projection));
+            }
+        }
+        let vertex = |index: &u32| processed[index];
+
+        let mut triangles = Vec::new();
+        let mut lines = Vec::new();
+        let mut points = Vec::new();
+        match primitive {
+            D3DPT_POINTLIST => points.extend(indices.iter().map(vertex)),
+            D3DPT_LINELIST => lines.extend(indices.chunks_exact(2).map(|l| (vertex(&l[0]), vertex(&l[1])))),
+            D3DPT_LINESTRIP => lines.extend(indices.windows(2).map(|l| (vertex(&l[0]), vertex(&l[1])))),
+            D3DPT_TRIANGLELIST => {
+                triangles.extend(indices.chunks_exact(3).map(|t| [vertex(&t[0]), vertex(&t[1]), vertex(&t[2])]))
+            }
+            // Every other triangle of a strip is wound the other way, and
+            // turned back
+            D3DPT_TRIANGLESTRIP => triangles.extend(indices.windows(3).enumerate().map(|(i, t)| {
+                if i % 2 == 0 {
+                    [vertex(&t[0]), vertex(&t[1]), vertex(&t[2])]
+                } else {
+                    [vertex(&t[1]), vertex(&t[0]), vertex(&t[2])]
+                }
+            })),
+            D3DPT_TRIANGLEFAN => triangles.extend(
+                indices.get(1..).unwrap_or(&[]).windows(2).map(|t| [vertex(&indices[0]), vertex(&t[0]), vertex(&t[1])]),
+            ),
+            _ => return Err(D3DERR_INVALIDCALL),
+        }
+
+        let flat = self.render_state(D3DRS_SHADEMODE) == D3DSHADE_FLAT;
+        let fill = self.render_state(D3DRS_FILLMODE);
+        let mut out_triangles = Vec::new();
+        let mut out_lines = Vec::new();
+        let mut out_points = Vec::new();
+        for mut triangle in triangles {
+            if flat {
+                let color = triangle[0].color;
+                triangle.iter_mut().for_each(|v| v.color = color);
+            }
+            let polygon = if layout.pretransformed { triangle.to_vec() } else { clip_polygon(triangle.to_vec()) };
+            let screen: Vec<raster::Vertex> =
+                polygon.iter().map(|v| self.screen_vertex(v, layout.pretransformed)).collect();
+            if screen.len() < 3 || self.culled(&screen[0], &screen[1], &screen[2]) {
+                continue;
+            }
+            match fill {
+                D3DFILL_POINT => out_points.extend_from_slice(&screen),
+                D3DFILL_WIREFRAME => {
+                    for (i, from) in screen.iter().enumerate() {
+                        out_lines.extend_from_slice(&[*from, screen[(i + 1) % screen.len()]]);
+                    }
+                }
+                _ => {
+                    for i in 1..screen.len() - 1 {
+                        out_triangles.extend_from_slice(&[screen[0], screen[i], screen[i + 1]]);
+                    }
+                }
+            }
+        }
+        for (a, b) in lines {
+            let b = if flat { Processed { color: a.color, ..b } } else { b };
+            let clipped = if layout.pretransformed { Some((a, b)) } else { clip_line(a, b) };
+            if let Some((a, b)) = clipped {
+                out_lines.push(self.screen_vertex(&a, layout.pretransformed));
+                out_lines.push(self.screen_vertex(&b, layout.pretransformed));
+            }
+        }
+        for point in points {
+            if layout.pretransformed || clip_distances(&point).iter().all(|&d| d >= 0.0) {
+                out_points.push(self.screen_vertex(&point, layout.pretransformed));
+            }
+        }
+
+        self.emit_state();
+        self.frame.draw(Primitive::Triangles, &out_triangles);
+        self.frame.draw(Primitive::Lines, &out_lines);
+        self.frame.draw(Primitive::Points, &out_points);
+        Ok(())
+    }
+
+    // Run the frame recorded since the last Present and show it
+    fn present(&mut self) -> Result<(), HRESULT> {
+        if self.in_scene {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        let frame = core::mem::take(&mut self.frame).build();
+        self.emitted = None;
+
+        if let Some(target) = &self.gpu {
+            let (width, height) = (self.params.back_buffer_width, self.params.back_buffer_height);
+            let mut gpu = target.gpu.lock();
+            let shown = gpu
+                .execute_3d(&frame, &target.framebuffer, width, height)
+                .and_then(|_| gpu.present_framebuffer(&target.framebuffer));
+            if shown.is_ok() {
+                return Ok(());
+            }
+        }
+
+        raster::execute(&frame, &mut self.target).map_err(|_| D3DERR_INVALIDCALL)?;
+        if let Some(id) = self.window {
+            surface::show(id, &self.target, self.params.back_buffer_format != D3DFMT_A8R8G8B8);
+        }
+        Ok(())
+    }
+}
+
+// Fill in what the app left for Direct3D to choose and check the rest
+fn settle_parameters(params: &mut PresentParameters, focus_window: Handle) -> Result<(), HRESULT> {
+    let windowed = params.windowed != 0;
+    if params.back_buffer_width == 0 || params.back_buffer_height == 0 {
+        if !windowed {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        let window = if params.device_window == Handle::NULL { focus_window } else { params.device_window };
+        let (width, height) = surface::client_size(window).unwrap_or((640, 480));
+        if params.back_buffer_width == 0 {
+            params.back_buffer_width = width;
+        }
+        if params.back_buffer_height == 0 {
+            params.back_buffer_height = height;
+        }
+    }
+    if params.back_buffer_format == D3DFMT_UNKNOWN && windowed {
+        params.back_buffer_format = D3DFMT_X8R8G8B8;
+    }
+    if params.back_buffer_count == 0 {
+        params.back_buffer_count = 1;
+    }
+    let depth_formats = [D3DFMT_D16, D3DFMT_D24X8, D3DFMT_D24S8, D3DFMT_D32];
+    let valid = [D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8].contains(&params.back_buffer_format)
+        && params.multi_sample_type == D3DMULTISAMPLE_NONE
+        && (D3DSWAPEFFECT_DISCARD..=D3DSWAPEFFECT_COPY).contains(&params.swap_effect)
+        && (params.enable_auto_depth_stencil == 0 || depth_formats.contains(&params.auto_depth_stencil_format))
+        && params.back_buffer_width <= 8192
+        && params.back_buffer_height <= 8192;
+    if !valid {
+        return Err(D3DERR_INVALIDCALL);
+    }
+    Ok(())
+}
+
+static DEVICES: Mutex<BTreeMap<u64, Device>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Buffer {
+    // The index format of an index buffer, or None for a vertex buffer
+    index_format: Option<u32>,
+    fvf: u32,
+    usage: u32,
+    pool: u32,
+    data: Box<[u8]>,
+    locks: u32,
+    priority: u32,
+}
+
+static BUFFERS: Mutex<BTreeMap<u64, Buffer>> = Mutex::new(BTreeMap::new());
+
+#[repr(C)]
+pub struct Direct3D9 {
+    vtbl: *const Direct3DVtbl,
+    ref_count: AtomicU32,
+}
+
+#[repr(C)]
+struct Device9 {
+    vtbl: *const DeviceVtbl,
+    ref_count: AtomicU32,
+    id: u64,
+}
+
+// A vertex or index buffer; both have the same methods
+#[repr(C)]
+struct Buffer9 {
+    vtbl: *const BufferVtbl,
+    ref_count: AtomicU32,
+    id: u64,
+    device: *mut Device9,
+    index: bool,
+}
+
+// Methods with nothing behind them, which fail whatever they are given
+type DeviceStub = unsafe extern "system" fn(*mut Device9) -> HRESULT;
+type BufferStub = unsafe extern "system" fn(*mut Buffer9) -> HRESULT;
+
+#[repr(C)]
+struct Direct3DVtbl {
+    query_interface: unsafe extern "system" fn(*mut Direct3D9, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut Direct3D9) -> u32,
+    release: unsafe extern "system" fn(*mut Direct3D9) -> u32,
+    register_software_device: unsafe extern "system" fn(*mut Direct3D9, *mut c_void) -> HRESULT,
+    get_adapter_count: unsafe extern "system" fn(*mut Direct3D9) -> u32,
+    get_adapter_identifier: unsafe extern "system" fn(*mut Direct3D9, u32, u32, *mut AdapterIdentifier) -> HRESULT,
+    get_adapter_mode_count: unsafe extern "system" fn(*mut Direct3D9, u32, u32) -> u32,
+    enum_adapter_modes: unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, *mut DisplayMode) -> HRESULT,
+    get_adapter_display_mode: unsafe extern "system" fn(*mut Direct3D9, u32, *mut DisplayMode) -> HRESULT,
+    check_device_type: unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, u32, BOOL) -> HRESULT,
+    check_device_format: unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, u32, u32, u32) -> HRESULT,
+    check_device_multi_sample_type:
+        unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, BOOL, u32, *mut u32) -> HRESULT,
+    check_depth_stencil_match: unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, u32, u32) -> HRESULT,
+    check_device_format_conversion: unsafe extern "system" fn(*mut Direct3D9, u32, u32, u32, u32) -> HRESULT,
+    get_device_caps: unsafe extern "system" fn(*mut Direct3D9, u32, u32, *mut Caps) -> HRESULT,
+    get_adapter_monitor: unsafe extern "system" fn(*mut Direct3D9, u32) -> Handle,
+    create_device: unsafe extern "system" fn(
+        *mut Direct3D9,
+        u32,
+        u32,
+        Handle,
+        u32,
+        *mut PresentParameters,
+        *mut *mut Device9,
+    ) -> HRESULT,
+}
+
+#[repr(C)]
+struct DeviceVtbl {
+    query_interface: unsafe extern "system" fn(*mut Device9, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut Device9) -> u32,
+    release: unsafe extern "system" fn(*mut Device9) -> u32,
+    test_cooperative_level: unsafe extern "system" fn(*mut Device9) -> HRESULT,
+    get_available_texture_mem: unsafe extern "system" fn(*mut Device9) -> u32,
+    evict_managed_resources: unsafe extern "system" fn(*mut Device9) -> HRESULT,
+    get_direct3d: unsafe extern "system" fn(*mut Device9, *mut *mut Direct3D9) -> HRESULT,
+    get_device_caps: unsafe extern "system" fn(*mut Device9, *mut Caps) -> HRESULT,
+    get_display_mode: unsafe extern "system" fn(*mut Device9, u32, *mut DisplayMode) -> HRESULT,
+    get_creation_parameters: unsafe extern "system" fn(*mut Device9, *mut CreationParameters) -> HRESULT,
+    set_cursor_properties: DeviceStub,
+    set_cursor_position: unsafe extern "system" fn(*mut Device9, i32, i32, u32),
+    show_cursor: unsafe extern "system" fn(*mut Device9, BOOL) -> BOOL,
+    create_additional_swap_chain: DeviceStub,
+    get_swap_chain: DeviceStub,
+    get_number_of_swap_chains: unsafe extern "system" fn(*mut Device9) -> u32,
+    reset: unsafe extern "system" fn(*mut Device9, *mut PresentParameters) -> HRESULT,
+    present: unsafe extern "system" fn(*mut Device9, *const Rect, *const Rect, Handle, *const c_void) -> HRESULT,
+    get_back_buffer: DeviceStub,
+    get_raster_status: unsafe extern "system" fn(*mut Device9, u32, *mut RasterStatus) -> HRESULT,
+    set_dialog_box_mode: unsafe extern "system" fn(*mut Device9, BOOL) -> HRESULT,
+    set_gamma_ramp: unsafe extern "system" fn(*mut Device9, u32, u32, *const GammaRamp),
+    get_gamma_ramp: unsafe extern "system" fn(*mut Device9, u32, *mut GammaRamp),
+    create_texture: DeviceStub,
+    create_volume_texture: DeviceStub,
+    create_cube_texture: DeviceStub,
+    create_vertex_buffer:
+        unsafe extern "system" fn(*mut Device9, u32, u32, u32, u32, *mut *mut Buffer9, *mut Handle) -> HRESULT,
+    create_index_buffer:
+        unsafe extern "system" fn(*mut Device9, u32, u32, u32, u32, *mut *mut Buffer9, *mut Handle) -> HRESULT,
+    create_render_target: DeviceStub,
+    create_depth_stencil_surface: DeviceStub,
+    update_surface: DeviceStub,
+    update_texture: DeviceStub,
+    get_render_target_data: DeviceStub,
+    get_front_buffer_data: DeviceStub,
+    stretch_rect: DeviceStub,
+    color_fill: DeviceStub,
+    create_offscreen_plain_surface: DeviceStub,
+    set_render_target: DeviceStub,
+    get_render_target: DeviceStub,
+    set_depth_stencil_surface: DeviceStub,
+    get_depth_stencil_surface: DeviceStub,
+    begin_scene: unsafe extern "system" fn(*mut Device9) -> HRESULT,
+    end_scene: unsafe extern "system" fn(*mut Device9) -> HRESULT,
+    clear: unsafe extern "system" fn(*mut Device9, u32, *const Rect, u32, u32, f32, u32) -> HRESULT,
+    set_transform: unsafe extern "system" fn(*mut Device9, u32, *const Matrix) -> HRESULT,
+    get_transform: unsafe extern "system" fn(*mut Device9, u32, *mut Matrix) -> HRESULT,
+    multiply_transform: unsafe extern "system" fn(*mut Device9, u32, *const Matrix) -> HRESULT,
+    set_viewport: unsafe extern "system" fn(*mut Device9, *const Viewport) -> HRESULT,
+    get_viewport: unsafe extern "system" fn(*mut Device9, *mut Viewport) -> HRESULT,
+    set_material: unsafe extern "system" fn(*mut Device9, *const Material) -> HRESULT,
+    get_material: unsafe extern "system" fn(*mut Device9, *mut Material) -> HRESULT,
+    set_light: unsafe extern "system" fn(*mut Device9, u32, *const Light) -> HRESULT,
+    get_light: unsafe extern "system" fn(*mut Device9, u32, *mut Light) -> HRESULT,
+    light_enable: unsafe extern "system" fn(*mut Device9, u32, BOOL) -> HRESULT,
+    get_light_enable: unsafe extern "system" fn(*mut Device9, u32, *mut BOOL) -> HRESULT,
+    set_clip_plane: DeviceStub,
+    get_clip_plane: DeviceStub,
+    set_render_state: unsafe extern "system" fn(*mut Device9, u32, u32) -> HRESULT,
+    get_render_state: unsafe extern "system" fn(*mut Device9, u32, *mut u32) -> HRESULT,
+    create_state_block: DeviceStub,
+    begin_state_block: DeviceStub,
+    end_state_block: DeviceStub,
+    set_clip_status: DeviceStub,
+    get_clip_status: DeviceStub,
+    get_texture: unsafe extern "system" fn(*mut Device9, u32, *mut *mut c_void) -> HRESULT,
+    set_texture: unsafe extern "system" fn(*mut Device9, u32, *mut c_void) -> HRESULT,
+    get_texture_stage_state: unsafe extern "system" fn(*mut Device9, u32, u32, *mut u32) -> HRESULT,
+    set_texture_stage_state: unsafe extern "system" fn(*mut Device9, u32, u32, u32) -> HRESULT,
+    get_sampler_state: unsafe extern "system" fn(*mut Device9, u32, u32, *mut u32) -> HRESULT,
+    set_sampler_state: unsafe extern "system" fn(*mut Device9, u32, u32, u32) -> HRESULT,
+    validate_device: unsafe extern "system" fn(*mut Device9, *mut u32) -> HRESULT,
+    set_palette_entries: DeviceStub,
+    get_palette_entries: DeviceStub,
+    set_current_texture_palette: DeviceStub,
+    get_current_texture_palette: DeviceStub,
+    set_scissor_rect: unsafe extern "system" fn(*mut Device9, *const Rect) -> HRESULT,
+    get_scissor_rect: unsafe extern "system" fn(*mut Device9, *mut Rect) -> HRESULT,
+    set_software_vertex_processing: unsafe extern "system" fn(*mut Device9, BOOL) -> HRESULT,
+    get_software_vertex_processing: unsafe extern "system" fn(*mut Device9) -> BOOL,
+    set_npatch_mode: unsafe extern "system" fn(*mut Device9, f32) -> HRESULT,
+    get_npatch_mode: unsafe extern "system" fn(*mut Device9) -> f32,
+    draw_primitive: unsafe extern "system" fn(*mut Device9, u32, u32, u32) -> HRESULT,
+    draw_indexed_primitive: unsafe extern "system" fn(*mut Device9, u32, i32, u32, u32, u32, u32) -> HRESULT,
+    draw_primitive_up: unsafe extern "system" fn(*mut Device9, u32, u32, *const c_void, u32) -> HRESULT,
+    draw_indexed_primitive_up:
+        unsafe extern "system" fn(*mut Device9, u32, u32, u32, u32, *const c_void, u32, *const c_void, u32) -> HRESULT,
+    process_vertices: DeviceStub,
+    create_vertex_declaration: DeviceStub,
+    set_vertex_declaration: unsafe extern "system" fn(*mut Device9, *mut c_void) -> HRESULT,
+    get_vertex_declaration: unsafe extern "system" fn(*mut Device9, *mut *mut c_void) -> HRESULT,
+    set_fvf: unsafe extern "system" fn(*mut Device9, u32) -> HRESULT,
+    get_fvf: unsafe extern "system" fn(*mut Device9, *mut u32) -> HRESULT,
+    create_vertex_shader: DeviceStub,
+    set_vertex_shader: unsafe extern "system" fn(*mut Device9, *mut c_void) -> HRESULT,
+    get_vertex_shader: unsafe extern "system" fn(*mut Device9, *mut *mut c_void) -> HRESULT,
+    set_vertex_shader_constant_f: DeviceStub,
+    get_vertex_shader_constant_f: DeviceStub,
+    set_vertex_shader_constant_i: DeviceStub,
+    get_vertex_shader_constant_i: DeviceStub,
+    set_vertex_shader_constant_b: DeviceStub,
+    get_vertex_shader_constant_b: DeviceStub,
+    set_stream_source: unsafe extern "system" fn(*mut Device9, u32, *mut Buffer9, u32, u32) -> HRESULT,
+    get_stream_source: unsafe extern "system" fn(*mut Device9, u32, *mut *mut Buffer9, *mut u32, *mut u32) -> HRESULT,
+    set_stream_source_freq: unsafe extern "system" fn(*mut Device9, u32, u32) -> HRESULT,
+    get_stream_source_freq: unsafe extern "system" fn(*mut Device9, u32, *mut u32) -> HRESULT,
+    set_indices: unsafe extern "system" fn(*mut Device9, *mut Buffer9) -> HRESULT,
+    get_indices: unsafe extern "system" fn(*mut Device9, *mut *mut Buffer9) -> HRESULT,
+    create_pixel_shader: DeviceStub,
+    set_pixel_shader: unsafe extern "system" fn(*mut Device9, *mut c_void) -> HRESULT,
+    get_pixel_shader: unsafe extern "system" fn(*mut Device9, *mut *mut c_void) -> HRESULT,
+    set_pixel_shader_constant_f: DeviceStub,
+    get_pixel_shader_constant_f: DeviceStub,
+    set_pixel_shader_constant_i: DeviceStub,
+    get_pixel_shader_constant_i: DeviceStub,
+    set_pixel_shader_constant_b: DeviceStub,
+    get_pixel_shader_constant_b: DeviceStub,
+    draw_rect_patch: DeviceStub,
+    draw_tri_patch: DeviceStub,
+    delete_patch: DeviceStub,
+    create_query: DeviceStub,
+}
+
+#[repr(C)]
+struct BufferVtbl {
+    query_interface: unsafe extern "system" fn(*mut Buffer9, REFIID, *mut LPVOID) -> HRESULT,
+    add_ref: unsafe extern "system" fn(*mut Buffer9) -> u32,
+    release: unsafe extern "system" fn(*mut Buffer9) -> u32,
+    get_device: unsafe extern "system" fn(*mut Buffer9, *mut *mut Device9) -> HRESULT,
+    set_private_data: BufferStub,
+    get_private_data: BufferStub,
+    free_private_data: BufferStub,
+    set_priority: unsafe extern "system" fn(*mut Buffer9, u32) -> u32,
+    get_priority: unsafe extern "system" fn(*mut Buffer9) -> u32,
+    preload: unsafe extern "system" fn(*mut Buffer9),
+    get_type: unsafe extern "system" fn(*mut Buffer9) -> u32,
+    lock: unsafe extern "system" fn(*mut Buffer9, u32, u32, *mut *mut c_void, u32) -> HRESULT,
+    unlock: unsafe extern "system" fn(*mut Buffer9) -> HRESULT,
+    // A VertexBufferDesc or an IndexBufferDesc
+    get_desc: unsafe extern "system" fn(*mut Buffer9, *mut c_void) -> HRESULT,
+}
+
+static DIRECT3D_VTBL: Direct3DVtbl = Direct3DVtbl {
+    query_interface: d3d_query_interface,
+    add_ref: d3d_add_ref,
+    release: d3d_release,
+    register_software_device: d3d_register_software_device,
+    get_adapter_count: d3d_get_adapter_count,
+    get_adapter_identifier: d3d_get_adapter_identifier,
+    get_adapter_mode_count: d3d_get_adapter_mode_count,
+    enum_adapter_modes: d3d_enum_adapter_modes,
+    get_adapter_display_mode: d3d_get_adapter_display_mode,
+    check_device_type: d3d_check_device_type,
+    check_device_format: d3d_check_device_format,
+    check_device_multi_sample_type: d3d_check_device_multi_sample_type,
+    check_depth_stencil_match: d3d_check_depth_stencil_match,
+    check_device_format_conversion: d3d_check_device_format_conversion,
+    get_device_caps: d3d_get_device_caps,
+    get_adapter_monitor: d3d_get_adapter_monitor,
+    create_device: d3d_create_device,
+};
+
+static DEVICE_VTBL: DeviceVtbl = DeviceVtbl {
+    query_interface: device_query_interface,
+    add_ref: device_add_ref,
+    release: device_release,
+    test_cooperative_level: device_test_cooperative_level,
+    get_available_texture_mem: device_get_available_texture_mem,
+    evict_managed_resources: device_evict_managed_resources,
+    get_direct3d: device_get_direct3d,
+    get_device_caps: device_get_device_caps,
+    get_display_mode: device_get_display_mode,
+    get_creation_parameters: device_get_creation_parameters,
+    set_cursor_properties: device_unavailable,
+    set_cursor_position: device_set_cursor_position,
+    show_cursor: device_show_cursor,
+    create_additional_swap_chain: device_unavailable,
+    get_swap_chain: device_unavailable,
+    get_number_of_swap_chains: device_get_number_of_swap_chains,
+    reset: device_reset,
+    present: device_present,
+    get_back_buffer: device_unavailable,
+    get_raster_status: device_get_raster_status,
+    set_dialog_box_mode: device_set_dialog_box_mode,
+    set_gamma_ramp: device_set_gamma_ramp,
+    get_gamma_ramp: device_get_gamma_ramp,
+    create_texture: device_unavailable,
+    create_volume_texture: device_unavailable,
+    create_cube_texture: device_unavailable,
+    create_vertex_buffer: device_create_vertex_buffer,
+    create_index_buffer: device_create_index_buffer,
+    create_render_target: device_unavailable,
+    create_depth_stencil_surface: device_unavailable,
+    update_surface: device_unavailable,
+    update_texture: device_unavailable,
+    get_render_target_data: device_unavailable,
+    get_front_buffer_data: device_unavailable,
+    stretch_rect: device_unavailable,
+    color_fill: device_unavailable,
+    create_offscreen_plain_surface: device_unavailable,
+    set_render_target: device_unavailable,
+    get_render_target: device_unavailable,
+    set_depth_stencil_surface: device_unavailable,
+    get_depth_stencil_surface: device_unavailable,
+    begin_scene: device_begin_scene,
+    end_scene: device_end_scene,
+    clear: device_clear,
+    set_transform: device_set_transform,
+    get_transform: device_get_transform,
+    multiply_transform: device_multiply_transform,
+    set_viewport: device_set_viewport,
+    get_viewport: device_get_viewport,
+    set_material: device_set_material,
+    get_material: device_get_material,
+    set_light: device_set_light,
+    get_light: device_get_light,
+    light_enable: device_light_enable,
+    get_light_enable: device_get_light_enable,
+    set_clip_plane: device_unavailable,
+    get_clip_plane: device_unavailable,
+    set_render_state: device_set_render_state,
+    get_render_state: device_get_render_state,
+    create_state_block: device_unavailable,
+    begin_state_block: device_unavailable,
+    end_state_block: device_unavailable,
+    set_clip_status: device_unavailable,
+    get_clip_status: device_unavailable,
+    get_texture: device_get_texture,
+    set_texture: device_set_texture,
+    get_texture_stage_state: device_get_texture_stage_state,
+    set_texture_stage_state: device_set_texture_stage_state,
+    get_sampler_state: device_get_sampler_state,
+    set_sampler_state: device_set_sampler_state,
+    validate_device: device_validate_device,
+    set_palette_entries: device_unavailable,
+    get_palette_entries: device_unavailable,
+    set_current_texture_palette: device_unavailable,
+    get_current_texture_palette: device_unavailable,
+    set_scissor_rect: device_set_scissor_rect,
+    get_scissor_rect: device_get_scissor_rect,
+    set_software_vertex_processing: device_set_software_vertex_processing,
+    get_software_vertex_processing: device_get_software_vertex_processing,
+    set_npatch_mode: device_set_npatch_mode,
+    get_npatch_mode: device_get_npatch_mode,
+    draw_primitive: device_draw_primitive,
+    draw_indexed_primitive: device_draw_indexed_primitive,
+    draw_primitive_up: device_draw_primitive_up,
+    draw_indexed_primitive_up: device_draw_indexed_primitive_up,
+    process_vertices: device_unavailable,
+    create_vertex_declaration: device_unavailable,
+    set_vertex_declaration: device_set_unavailable,
+    get_vertex_declaration: device_get_null,
+    set_fvf: device_set_fvf,
+    get_fvf: device_get_fvf,
+    create_vertex_shader: device_unavailable,
+    set_vertex_shader: device_set_unavailable,
+    get_vertex_shader: device_get_null,
+    set_vertex_shader_constant_f: device_unavailable,
+    get_vertex_shader_constant_f: device_unavailable,
+    set_vertex_shader_constant_i: device_unavailable,
+    get_vertex_shader_constant_i: device_unavailable,
+    set_vertex_shader_constant_b: device_unavailable,
+    get_vertex_shader_constant_b: device_unavailable,
+    set_stream_source: device_set_stream_source,
+    get_stream_source: device_get_stream_source,
+    set_stream_source_freq: device_set_stream_source_freq,
+    get_stream_source_freq: device_get_stream_source_freq,
+    set_indices: device_set_indices,
+    get_indices: device_get_indices,
+    create_pixel_shader: device_unavailable,
+    set_pixel_shader: device_set_unavailable,
+    get_pixel_shader: device_get_null,
+    set_pixel_shader_constant_f: device_unavailable,
+    get_pixel_shader_constant_f: device_unavailable,
+    set_pixel_shader_constant_i: device_unavailable,
+    get_pixel_shader_constant_i: device_unavailable,
+    set_pixel_shader_constant_b: device_unavailable,
+    get_pixel_shader_constant_b: device_unavailable,
+    draw_rect_patch: device_unavailable,
+    draw_tri_patch: device_unavailable,
+    delete_patch: device_unavailable,
+    create_query: device_unavailable,
+};
+
+// Vertex and index buffers share their methods; the object says which it is
+static BUFFER_VTBL: BufferVtbl = BufferVtbl {
+    query_interface: buffer_query_interface,
+    add_ref: buffer_add_ref,
+    release: buffer_release,
+    get_device: buffer_get_device,
+    set_private_data: buffer_unavailable,
+    get_private_data: buffer_unavailable,
+    free_private_data: buffer_unavailable,
+    set_priority: buffer_set_priority,
+    get_priority: buffer_get_priority,
+    preload: buffer_preload,
+    get_type: buffer_get_type,
+    lock: buffer_lock,
+    unlock: buffer_unlock,
+    get_desc: buffer_get_desc,
+};
+
+unsafe fn with_device<T>(this: *mut Device9, f: impl FnOnce(&mut Device) -> Result<T, HRESULT>) -> Result<T, HRESULT> {
+    if this.is_null() {
+        return Err(E_POINTER);
+    }
+    let mut devices = DEVICES.lock();
+    f(devices.get_mut(&(*this).id).ok_or(D3DERR_INVALIDCALL)?)
+}
+
+unsafe fn with_buffer<T>(this: *mut Buffer9, f: impl FnOnce(&mut Buffer) -> Result<T, HRESULT>) -> Result<T, HRESULT> {
+    if this.is_null() {
+        return Err(E_POINTER);
+    }
+    let mut buffers = BUFFERS.lock();
+    f(buffers.get_mut(&(*this).id).ok_or(D3DERR_INVALIDCALL)?)
+}
+
+// Store `value` through `out`, which the app may have left null
+unsafe fn put<T>(out: *mut T, value: T) -> Result<(), HRESULT> {
+    if out.is_null() {
+        return Err(D3DERR_INVALIDCALL);
+    }
+    out.write(value);
+    Ok(())
+}
+
+// Read what `from` points to, which the app may have left null
+unsafe fn get<T: Copy>(from: *const T) -> Result<T, HRESULT> {
+    if from.is_null() {
+        return Err(D3DERR_INVALIDCALL);
+    }
+    Ok(from.read_unaligned())
+}
+
+fn result(result: Result<(), HRESULT>) -> HRESULT {
+    result.err().unwrap_or(D3D_OK)
+}
+
+// The desktop's mode, which is the only one there is
+fn display_mode() -> DisplayMode {
+    let compositor = crate::graphics::compositor::COMPOSITOR.lock();
+    let (width, height) = compositor
+        .as_ref()
+        .map_or((800, 600), |compositor| (compositor.screen().width() as u32, compositor.screen().height() as u32));
+    DisplayMode { width, height, refresh_rate: 60, format: D3DFMT_X8R8G8B8 }
+}
+
+// The modes a full-screen device may ask for: the common ones that fit the
+// desktop, and the desktop's own
+fn display_modes() -> Vec<DisplayMode> {
+    let desktop = display_mode();
+    let mut modes: Vec<DisplayMode> = [(640, 480), (800, 600), (1024, 768), (1280, 720), (1280, 1024), (1920, 1080)]
+        .into_iter()
+        .filter(|&(width, height)| width <= desktop.width && height <= desktop.height)
+        .filter(|&(width, height)| (width, height) != (desktop.width, desktop.height))
+        .map(|(width, height)| DisplayMode { width, height, ..desktop })
+        .collect();
+    modes.push(desktop);
+    modes
+}
+
+fn caps(adapter: u32, device_type: u32) -> Caps {
+    Caps {
+        device_type,
+        adapter_ordinal: adapter,
+        caps2: D3DCAPS2_CANRENDERWINDOWED,
+        presentation_intervals: D3DPRESENT_INTERVAL_ONE | D3DPRESENT_INTERVAL_IMMEDIATE,
+        dev_caps: D3DDEVCAPS_EXECUTESYSTEMMEMORY
+            | D3DDEVCAPS_TLVERTEXSYSTEMMEMORY
+            | D3DDEVCAPS_DRAWPRIMTLVERTEX
+            | D3DDEVCAPS_HWTRANSFORMANDLIGHT
+            | D3DDEVCAPS_HWRASTERIZATION,
+        primitive_misc_caps: D3DPMISCCAPS_CULLNONE | D3DPMISCCAPS_CULLCW | D3DPMISCCAPS_CULLCCW,
+        raster_caps: D3DPRASTERCAPS_ZTEST | D3DPRASTERCAPS_SCISSORTEST,
+        z_cmp_caps: D3DPCMPCAPS_ALL,
+        src_blend_caps: D3DPBLENDCAPS_ALL,
+        dest_blend_caps: D3DPBLENDCAPS_ALL,
+        shade_caps: D3DPSHADECAPS_COLORGOURAUDRGB,
+        line_caps: D3DLINECAPS_ZTEST | D3DLINECAPS_BLEND,
+        max_vertex_w: 1.0e10,
+        guard_band_left: -32768.0,
+        guard_band_top: -32768.0,
+        guard_band_right: 32767.0,
+        guard_band_bottom: 32767.0,
+        vertex_processing_caps: D3DVTXPCAPS_MATERIALSOURCE7
+            | D3DVTXPCAPS_DIRECTIONALLIGHTS
+            | D3DVTXPCAPS_POSITIONALLIGHTS,
+        max_active_lights: MAX_ACTIVE_LIGHTS as u32,
+        max_point_size: 1.0,
+        max_primitive_count: 0xF_FFFF,
+        max_vertex_index: 0xFF_FFFF,
+        max_streams: 1,
+        max_stream_stride: 1024,
+        // Version 0.0 of each: no shaders
+        vertex_shader_version: 0xFFFE_0000,
+        pixel_shader_version: 0xFFFF_0000,
+        number_of_adapters_in_group: 1,
+        num_simultaneous_rts: 1,
+        ..Caps::default()
+    }
+}
+
+fn check_adapter(adapter: u32, device_type: u32) -> Result<(), HRESULT> {
+    if adapter != D3DADAPTER_DEFAULT {
+        return Err(D3DERR_INVALIDCALL);
+    }
+    if device_type != D3DDEVTYPE_HAL && device_type != D3DDEVTYPE_REF {
+        return Err(D3DERR_NOTAVAILABLE);
+    }
+    Ok(())
+}
+
+// How many vertices `count` primitives of a type take
+fn vertex_count(primitive: u32, count: u32) -> Option<u32> {
+    match primitive {
+        D3DPT_POINTLIST => Some(count),
+        D3DPT_LINELIST => count.checked_mul(2),
+        D3DPT_LINESTRIP => count.checked_add(1),
+        D3DPT_TRIANGLELIST => count.checked_mul(3),
+        D3DPT_TRIANGLESTRIP | D3DPT_TRIANGLEFAN => count.checked_add(2),
+        _ => None,
+    }
+}
+
+// `count` indices of `format` from `data`
+fn read_indices(data: &[u8], format: u32, count: usize) -> Result<Vec<u32>, HRESULT> {
+    let size = if format == D3DFMT_INDEX32 { 4 } else { 2 };
+    let data = data.get(..count.checked_mul(size).ok_or(D3DERR_INVALIDCALL)?).ok_or(D3DERR_INVALIDCALL)?;
+    Ok(data
+        .chunks_exact(size)
+        .map(|index| if size == 4 { dword(index, 0) } else { u16::from_le_bytes([index[0], index[1]]) as u32 })
+        .collect())
+}
+
+// IDirect3D9
+
+unsafe extern "system" fn d3d_query_interface(this: *mut Direct3D9, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() || riid.is_null() || out.is_null() {
+        return E_POINTER;
+    }
+    if ![GUID::IID_IUnknown, IID_IDIRECT3D9].contains(&*riid) {
+        *out = null_mut();
+        return E_NOINTERFACE;
+    }
+    d3d_add_ref(this);
+    *out = this.cast();
+    S_OK
+}
+
+unsafe extern "system" fn d3d_add_ref(this: *mut Direct3D9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+unsafe extern "system" fn d3d_release(this: *mut Direct3D9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    let count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+    if count == 0 {
+        drop(Box::from_raw(this));
+    }
+    count
+}
+
+unsafe extern "system" fn d3d_register_software_device(_this: *mut Direct3D9, _init: *mut c_void) -> HRESULT {
+    D3DERR_NOTAVAILABLE
+}
+
+unsafe extern "system" fn d3d_get_adapter_count(_this: *mut Direct3D9) -> u32 {
+    1
+}
+
+unsafe extern "system" fn d3d_get_adapter_identifier(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    _flags: u32,
+    out: *mut AdapterIdentifier,
+) -> HRESULT {
+    if adapter != D3DADAPTER_DEFAULT || out.is_null() {
+        return D3DERR_INVALIDCALL;
+    }
+    let (description, vendor_id, device_id) = match GPU_MANAGER.read().get_primary_gpu() {
+        Some(gpu) => {
+            let gpu = gpu.lock();
+            (String::from(gpu.name()), gpu.vendor_id() as u32, gpu.device_id() as u32)
+        }
+        None => (String::from(SOFTWARE_ADAPTER), 0, 0),
+    };
+    let mut identifier = AdapterIdentifier {
+        driver: [0; 512],
+        description: [0; 512],
+        device_name: [0; 32],
+        driver_version: 0,
+        vendor_id,
+        device_id,
+        sub_sys_id: 0,
+        revision: 0,
+        device_identifier: GUID { data1: vendor_id, data2: device_id as u16, ..GUID::NULL },
+        whql_level: 0,
+    };
+    for (to, from) in [
+        (&mut identifier.driver[..], "d3d9.dll"),
+        (&mut identifier.description[..], description.as_str()),
+        (&mut identifier.device_name[..], "\\\\.\\DISPLAY1"),
+    ] {
+        let length = from.len().min(to.len() - 1);
+        to[..length].copy_from_slice(&from.as_bytes()[..length]);
+    }
+    out.write(identifier);
+    D3D_OK
+}
+
+unsafe extern "system" fn d3d_get_adapter_mode_count(_this: *mut Direct3D9, adapter: u32, format: u32) -> u32 {
+    if adapter != D3DADAPTER_DEFAULT || format != D3DFMT_X8R8G8B8 {
+        return 0;
+    }
+    display_modes().len() as u32
+}
+
+unsafe extern "system" fn d3d_enum_adapter_modes(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    format: u32,
+    mode: u32,
+    out: *mut DisplayMode,
+) -> HRESULT {
+    if adapter != D3DADAPTER_DEFAULT {
+        return D3DERR_INVALIDCALL;
+    }
+    if format != D3DFMT_X8R8G8B8 {
+        return D3DERR_NOTAVAILABLE;
+    }
+    match display_modes().get(mode as usize) {
+        Some(found) => result(put(out, *found)),
+        None => D3DERR_INVALIDCALL,
+    }
+}
+
+unsafe extern "system" fn d3d_get_adapter_display_mode(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    out: *mut DisplayMode,
+) -> HRESULT {
+    if adapter != D3DADAPTER_DEFAULT {
+        return D3DERR_INVALIDCALL;
+    }
+    result(put(out, display_mode()))
+}
+
+unsafe extern "system" fn d3d_check_device_type(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    display_format: u32,
+    back_buffer_format: u32,
+    _windowed: BOOL,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    let back_buffer_ok = [D3DFMT_UNKNOWN, D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8].contains(&back_buffer_format);
+    if display_format != D3DFMT_X8R8G8B8 || !back_buffer_ok {
+        return D3DERR_NOTAVAILABLE;
+    }
+    D3D_OK
+}
+
+unsafe extern "system" fn d3d_check_device_format(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    display_format: u32,
+    usage: u32,
+    resource_type: u32,
+    format: u32,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    let supported = display_format == D3DFMT_X8R8G8B8
+        && match resource_type {
+            D3DRTYPE_SURFACE if usage & D3DUSAGE_DEPTHSTENCIL != 0 => {
+                [D3DFMT_D16, D3DFMT_D24X8, D3DFMT_D24S8, D3DFMT_D32].contains(&format)
+            }
+            D3DRTYPE_SURFACE if usage & D3DUSAGE_RENDERTARGET != 0 => {
+                [D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8].contains(&format)
+            }
+            D3DRTYPE_VERTEXBUFFER => format == D3DFMT_VERTEXDATA,
+            D3DRTYPE_INDEXBUFFER => [D3DFMT_INDEX16, D3DFMT_INDEX32].contains(&format),
+            _ => false,
+        };
+    if supported {
+        D3D_OK
+    } else {
+        D3DERR_NOTAVAILABLE
+    }
+}
+
+unsafe extern "system" fn d3d_check_device_multi_sample_type(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    _format: u32,
+    _windowed: BOOL,
+    multi_sample_type: u32,
+    quality_levels: *mut u32,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    if multi_sample_type != D3DMULTISAMPLE_NONE {
+        return D3DERR_NOTAVAILABLE;
+    }
+    if !quality_levels.is_null() {
+        *quality_levels = 1;
+    }
+    D3D_OK
+}
+
+unsafe extern "system" fn d3d_check_depth_stencil_match(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    _display_format: u32,
+    render_target_format: u32,
+    depth_stencil_format: u32,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    let matches = [D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8].contains(&render_target_format)
+        && [D3DFMT_D16, D3DFMT_D24X8, D3DFMT_D24S8, D3DFMT_D32].contains(&depth_stencil_format);
+    if matches {
+        D3D_OK
+    } else {
+        D3DERR_NOTAVAILABLE
+    }
+}
+
+unsafe extern "system" fn d3d_check_device_format_conversion(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    source_format: u32,
+    target_format: u32,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    let formats = [D3DFMT_X8R8G8B8, D3DFMT_A8R8G8B8];
+    if formats.contains(&source_format) && formats.contains(&target_format) {
+        D3D_OK
+    } else {
+        D3DERR_NOTAVAILABLE
+    }
+}
+
+unsafe extern "system" fn d3d_get_device_caps(
+    _this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    out: *mut Caps,
+) -> HRESULT {
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    result(put(out, caps(adapter, device_type)))
+}
+
+unsafe extern "system" fn d3d_get_adapter_monitor(_this: *mut Direct3D9, adapter: u32) -> Handle {
+    if adapter != D3DADAPTER_DEFAULT {
+        return Handle::NULL;
+    }
+    // The one monitor
+    Handle(1)
+}
+
+unsafe extern "system" fn d3d_create_device(
+    this: *mut Direct3D9,
+    adapter: u32,
+    device_type: u32,
+    focus_window: Handle,
+    behavior: u32,
+    params: *mut PresentParameters,
+    out: *mut *mut Device9,
+) -> HRESULT {
+    if this.is_null() || params.is_null() || out.is_null() {
+        return D3DERR_INVALIDCALL;
+    }
+    *out = null_mut();
+    if let Err(error) = check_adapter(adapter, device_type) {
+        return error;
+    }
+    let processing =
+        D3DCREATE_SOFTWARE_VERTEXPROCESSING | D3DCREATE_HARDWARE_VERTEXPROCESSING | D3DCREATE_MIXED_VERTEXPROCESSING;
+    if (behavior & processing).count_ones() != 1 {
+        return D3DERR_INVALIDCALL;
+    }
+    let mut settled = params.read_unaligned();
+    if let Err(error) = settle_parameters(&mut settled, focus_window) {
+        return error;
+    }
+    params.write_unaligned(settled);
+
+    let windowed = settled.windowed != 0;
+    let (width, height) = (settled.back_buffer_width, settled.back_buffer_height);
+    let title_window = if settled.device_window == Handle::NULL { focus_window } else { settled.device_window };
+    let window = surface::open(title_window, "Direct3D", width, height, windowed);
+    // Full-screen frames go to the GPU if it takes them; each falls back to
+    // the window if it does not
+    let gpu = (!windowed).then(|| GPU_MANAGER.read().get_primary_gpu()).flatten().and_then(|gpu| {
+        let framebuffer = gpu.lock().create_framebuffer(width, height).ok()?;
+        Some(GpuTarget { gpu, framebuffer })
+    });
+
+    d3d_add_ref(this);
+    let mut device = Device {
+        adapter,
+        device_type,
+        focus_window,
+        behavior,
+        params: settled,
+        target: RenderTarget::new(0, 0),
+        window,
+        gpu,
+        d3d: this,
+        frame: StreamBuilder::new(),
+        emitted: None,
+        in_scene: false,
+        world: Matrix::IDENTITY,
+        view: Matrix::IDENTITY,
+        projection: Matrix::IDENTITY,
+        texture_transforms: [Matrix::IDENTITY; 8],
+        viewport: Viewport::default(),
+        scissor: Rect::default(),
+        render_states: [0; RENDER_STATES],
+        texture_stage_states: BTreeMap::new(),
+        sampler_states: BTreeMap::new(),
+        material: Material::default(),
+        lights: BTreeMap::new(),
+        fvf: 0,
+        stream: None,
+        indices: None,
+        software_vertex_processing: behavior & D3DCREATE_SOFTWARE_VERTEXPROCESSING != 0,
+        cursor_shown: false,
+        gamma: GammaRamp::identity(),
+    };
+    device.reset_state();
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    DEVICES.lock().insert(id, device);
+    *out = Box::into_raw(Box::new(Device9 { vtbl: &DEVICE_VTBL, ref_count: AtomicU32::new(1), id }));
+    D3D_OK
+}
+
+// IDirect3DDevice9
+
+unsafe extern "system" fn device_unavailable(_this: *mut Device9) -> HRESULT {
+    D3DERR_NOTAVAILABLE
+}
+
+// Setting a shader or vertex declaration: only none will do
+unsafe extern "system" fn device_set_unavailable(_this: *mut Device9, object: *mut c_void) -> HRESULT {
+    if object.is_null() {
+        D3D_OK
+    } else {
+        D3DERR_NOTAVAILABLE
+    }
+}
+
+unsafe extern "system" fn device_get_null(_this: *mut Device9, out: *mut *mut c_void) -> HRESULT {
+    result(put(out, null_mut()))
+}
+
+unsafe extern "system" fn device_query_interface(this: *mut Device9, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() || riid.is_null() || out.is_null() {
+        return E_POINTER;
+    }
+    if ![GUID::IID_IUnknown, IID_IDIRECT3DDEVICE9].contains(&*riid) {
+        *out = null_mut();
+        return E_NOINTERFACE;
+    }
+    device_add_ref(this);
+    *out = this.cast();
+    S_OK
+}
+
+unsafe extern "system" fn device_add_ref(this: *mut Device9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+unsafe extern "system" fn device_release(this: *mut Device9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    let count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+    if count == 0 {
+        let device = DEVICES.lock().remove(&(*this).id);
+        if let Some(device) = device {
+            if let Some(id) = device.window {
+                surface::close(id);
+            }
+            if let Some(target) = device.gpu {
+                let _ = target.gpu.lock().free_buffer(target.framebuffer);
+            }
+            d3d_release(device.d3d);
+            // The buffers it held go as it drops
+        }
+        drop(Box::from_raw(this));
+    }
+    count
+}
+
+unsafe extern "system" fn device_test_cooperative_level(this: *mut Device9) -> HRESULT {
+    result(with_device(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn device_get_available_texture_mem(_this: *mut Device9) -> u32 {
+    TEXTURE_MEMORY
+}
+
+unsafe extern "system" fn device_evict_managed_resources(_this: *mut Device9) -> HRESULT {
+    D3D_OK
+}
+
+unsafe extern "system" fn device_get_direct3d(this: *mut Device9, out: *mut *mut Direct3D9) -> HRESULT {
+    result(with_device(this, |device| {
+        put(out, device.d3d)?;
+        d3d_add_ref(device.d3d);
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_device_caps(this: *mut Device9, out: *mut Caps) -> HRESULT {
+    result(with_device(this, |device| put(out, caps(device.adapter, device.device_type))))
+}
+
+unsafe extern "system" fn device_get_display_mode(
+    this: *mut Device9,
+    swap_chain: u32,
+    out: *mut DisplayMode,
+) -> HRESULT {
+    if swap_chain != 0 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |device| {
+        let mode = if device.params.windowed != 0 {
+            display_mode()
+        } else {
+            DisplayMode {
+                width: device.params.back_buffer_width,
+                height: device.params.back_buffer_height,
+                ..display_mode()
+            }
+        };
+        put(out, mode)
+    }))
+}
+
+unsafe extern "system" fn device_get_creation_parameters(this: *mut Device9, out: *mut CreationParameters) -> HRESULT {
+    result(with_device(this, |device| {
+        let params = CreationParameters {
+            adapter_ordinal: device.adapter,
+            device_type: device.device_type,
+            focus_window: device.focus_window,
+            behavior_flags: device.behavior,
+        };
+        put(out, params)
+    }))
+}
+
+unsafe extern "system" fn device_set_cursor_position(_this: *mut Device9, _x: i32, _y: i32, _flags: u32) {}
+
+unsafe extern "system" fn device_show_cursor(this: *mut Device9, show: BOOL) -> BOOL {
+    with_device(this, |device| Ok(core::mem::replace(&mut device.cursor_shown, show != 0) as BOOL)).unwrap_or(0)
+}
+
+unsafe extern "system" fn device_get_number_of_swap_chains(_this: *mut Device9) -> u32 {
+    1
+}
+
+unsafe extern "system" fn device_reset(this: *mut Device9, params: *mut PresentParameters) -> HRESULT {
+    if params.is_null() {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |device| {
+        let mut settled = params.read_unaligned();
+        settle_parameters(&mut settled, device.focus_window)?;
+        // A device stays windowed or full screen, with the window it has
+        if (settled.windowed != 0) != (device.params.windowed != 0) {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        params.write_unaligned(settled);
+        let (width, height) = (settled.back_buffer_width, settled.back_buffer_height);
+        let resized = (width, height) != (device.params.back_buffer_width, device.params.back_buffer_height);
+        device.params = settled;
+        device.reset_state();
+        if resized {
+            if let Some(id) = device.window {
+                surface::resize(id, width, height);
+            }
+            if let Some(target) = device.gpu.take() {
+                let mut gpu = target.gpu.lock();
+                let _ = gpu.free_buffer(target.framebuffer);
+                let framebuffer = gpu.create_framebuffer(width, height);
+                drop(gpu);
+                device.gpu = framebuffer.ok().map(|framebuffer| GpuTarget { gpu: target.gpu, framebuffer });
+            }
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_present(
+    this: *mut Device9,
+    _source: *const Rect,
+    _dest: *const Rect,
+    _dest_window: Handle,
+    _dirty: *const c_void,
+) -> HRESULT {
+    result(with_device(this, |device| device.present()))
+}
+
+unsafe extern "system" fn device_get_raster_status(
+    this: *mut Device9,
+    swap_chain: u32,
+    out: *mut RasterStatus,
+) -> HRESULT {
+    if swap_chain != 0 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |_| put(out, RasterStatus { in_vblank: 0, scan_line: 0 })))
+}
+
+unsafe extern "system" fn device_set_dialog_box_mode(this: *mut Device9, _enable: BOOL) -> HRESULT {
+    result(with_device(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn device_set_gamma_ramp(
+    this: *mut Device9,
+    swap_chain: u32,
+    _flags: u32,
+    ramp: *const GammaRamp,
+) {
+    if swap_chain != 0 {
+        return;
+    }
+    let _ = with_device(this, |device| {
+        device.gamma = get(ramp)?;
+        Ok(())
+    });
+}
+
+unsafe extern "system" fn device_get_gamma_ramp(this: *mut Device9, swap_chain: u32, out: *mut GammaRamp) {
+    if swap_chain != 0 {
+        return;
+    }
+    let _ = with_device(this, |device| put(out, device.gamma));
+}
+
+// Make a vertex or an index buffer of `length` bytes
+#[allow(clippy::too_many_arguments)]
+unsafe fn create_buffer(
+    this: *mut Device9,
+    length: u32,
+    usage: u32,
+    fvf: u32,
+    index_format: Option<u32>,
+    pool: u32,
+    out: *mut *mut Buffer9,
+    shared: *mut Handle,
+) -> HRESULT {
+    if this.is_null() || out.is_null() {
+        return D3DERR_INVALIDCALL;
+    }
+    *out = null_mut();
+    if !shared.is_null() || length == 0 || pool > D3DPOOL_SYSTEMMEM {
+        return D3DERR_INVALIDCALL;
+    }
+    if index_format.is_none() && fvf != 0 {
+        match Layout::of(fvf) {
+            Some(layout) if length as usize >= layout.size => {}
+            _ => return D3DERR_INVALIDCALL,
+        }
+    }
+    if let Err(error) = with_device(this, |_| Ok(())) {
+        return error;
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let buffer = Buffer {
+        index_format,
+        fvf,
+        usage,
+        pool,
+        data: vec![0; length as usize].into_boxed_slice(),
+        locks: 0,
+        priority: 0,
+    };
+    BUFFERS.lock().insert(id, buffer);
+    let index = index_format.is_some();
+    *out =
+        Box::into_raw(Box::new(Buffer9 { vtbl: &BUFFER_VTBL, ref_count: AtomicU32::new(1), id, device: this, index }));
+    D3D_OK
+}
+
+unsafe extern "system" fn device_create_vertex_buffer(
+    this: *mut Device9,
+    length: u32,
+    usage: u32,
+    fvf: u32,
+    pool: u32,
+    out: *mut *mut Buffer9,
+    shared: *mut Handle,
+) -> HRESULT {
+    create_buffer(this, length, usage, fvf, None, pool, out, shared)
+}
+
+unsafe extern "system" fn device_create_index_buffer(
+    this: *mut Device9,
+    length: u32,
+    usage: u32,
+    format: u32,
+    pool: u32,
+    out: *mut *mut Buffer9,
+    shared: *mut Handle,
+) -> HRESULT {
+    if format != D3DFMT_INDEX16 && format != D3DFMT_INDEX32 {
+        return D3DERR_INVALIDCALL;
+    }
+    create_buffer(this, length, usage, 0, Some(format), pool, out, shared)
+}
+
+unsafe extern "system" fn device_begin_scene(this: *mut Device9) -> HRESULT {
+    result(with_device(this, |device| {
+        if device.in_scene {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        device.in_scene = true;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_end_scene(this: *mut Device9) -> HRESULT {
+    result(with_device(this, |device| {
+        if !device.in_scene {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        device.in_scene = false;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_clear(
+    this: *mut Device9,
+    count: u32,
+    rects: *const Rect,
+    flags: u32,
+    color: u32,
+    z: f32,
+    _stencil: u32,
+) -> HRESULT {
+    let rects =
+        if count == 0 || rects.is_null() { &[][..] } else { core::slice::from_raw_parts(rects, count as usize) };
+    result(with_device(this, |device| device.clear(rects, flags, color, z)))
+}
+
+// The transform `state` names
+fn transform(device: &mut Device, state: u32) -> Result<&mut Matrix, HRESULT> {
+    match state {
+        D3DTS_WORLD => Ok(&mut device.world),
+        D3DTS_VIEW => Ok(&mut device.view),
+        D3DTS_PROJECTION => Ok(&mut device.projection),
+        D3DTS_TEXTURE0..=D3DTS_TEXTURE7 => Ok(&mut device.texture_transforms[(state - D3DTS_TEXTURE0) as usize]),
+        _ => Err(D3DERR_INVALIDCALL),
+    }
+}
+
+unsafe extern "system" fn device_set_transform(this: *mut Device9, state: u32, matrix: *const Matrix) -> HRESULT {
+    result(with_device(this, |device| {
+        *transform(device, state)? = get(matrix)?;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_transform(this: *mut Device9, state: u32, out: *mut Matrix) -> HRESULT {
+    result(with_device(this, |device| put(out, *transform(device, state)?)))
+}
+
+unsafe extern "system" fn device_multiply_transform(this: *mut Device9, state: u32, matrix: *const Matrix) -> HRESULT {
+    result(with_device(this, |device| {
+        let by = get(matrix)?;
+        let current = transform(device, state)?;
+        *current = by.then(current);
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_set_viewport(this: *mut Device9, viewport: *const Viewport) -> HRESULT {
+    result(with_device(this, |device| {
+        let viewport = get(viewport)?;
+        let fits = viewport.x.checked_add(viewport.width).is_some_and(|right| right <= device.params.back_buffer_width)
+            && viewport.y.checked_add(viewport.height).is_some_and(|bottom| bottom <= device.params.back_buffer_height)
+            && (0.0..=1.0).contains(&viewport.min_z)
+            && (0.0..=1.0).contains(&viewport.max_z);
+        if !fits {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        device.viewport = viewport;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_viewport(this: *mut Device9, out: *mut Viewport) -> HRESULT {
+    result(with_device(this, |device| put(out, device.viewport)))
+}
+
+unsafe extern "system" fn device_set_material(this: *mut Device9, material: *const Material) -> HRESULT {
+    result(with_device(this, |device| {
+        device.material = get(material)?;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_material(this: *mut Device9, out: *mut Material) -> HRESULT {
+    result(with_device(this, |device| put(out, device.material)))
+}
+
+unsafe extern "system" fn device_set_light(this: *mut Device9, index: u32, light: *const Light) -> HRESULT {
+    result(with_device(this, |device| {
+        let light = get(light)?;
+        if !(D3DLIGHT_POINT..=D3DLIGHT_DIRECTIONAL).contains(&light.light_type) {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        let enabled = device.lights.get(&index).is_some_and(|(_, enabled)| *enabled);
+        device.lights.insert(index, (light, enabled));
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_light(this: *mut Device9, index: u32, out: *mut Light) -> HRESULT {
+    result(with_device(this, |device| put(out, device.lights.get(&index).ok_or(D3DERR_INVALIDCALL)?.0)))
+}
+
+unsafe extern "system" fn device_light_enable(this: *mut Device9, index: u32, enable: BOOL) -> HRESULT {
+    result(with_device(this, |device| {
+        // Enabling a light never set gives it the default one: white,
+        // shining down +z
+        let default = Light {
+            light_type: D3DLIGHT_DIRECTIONAL,
+            diffuse: ColorValue { r: 1.0, g: 1.0, b: 1.0, a: 0.0 },
+            direction: Vector { x: 0.0, y: 0.0, z: 1.0 },
+            ..Light::default()
+        };
+        device.lights.entry(index).or_insert((default, false)).1 = enable != 0;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_light_enable(this: *mut Device9, index: u32, out: *mut BOOL) -> HRESULT {
+    result(with_device(this, |device| {
+        let (_, enabled) = device.lights.get(&index).ok_or(D3DERR_INVALIDCALL)?;
+        // Enabled lights report 128, as on Windows
+        put(out, if *enabled { 128 } else { 0 })
+    }))
+}
+
+unsafe extern "system" fn device_set_render_state(this: *mut Device9, state: u32, value: u32) -> HRESULT {
+    result(with_device(this, |device| {
+        *device.render_states.get_mut(state as usize).ok_or(D3DERR_INVALIDCALL)? = value;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_render_state(this: *mut Device9, state: u32, out: *mut u32) -> HRESULT {
+    result(with_device(this, |device| put(out, *device.render_states.get(state as usize).ok_or(D3DERR_INVALIDCALL)?)))
+}
+
+unsafe extern "system" fn device_get_texture(this: *mut Device9, _stage: u32, out: *mut *mut c_void) -> HRESULT {
+    result(with_device(this, |_| put(out, null_mut())))
+}
+
+unsafe extern "system" fn device_set_texture(this: *mut Device9, _stage: u32, texture: *mut c_void) -> HRESULT {
+    device_set_unavailable(this, texture)
+}
+
+unsafe extern "system" fn device_get_texture_stage_state(
+    this: *mut Device9,
+    stage: u32,
+    state: u32,
+    out: *mut u32,
+) -> HRESULT {
+    result(with_device(this, |device| put(out, device.texture_stage_states.get(&(stage, state)).copied().unwrap_or(0))))
+}
+
+unsafe extern "system" fn device_set_texture_stage_state(
+    this: *mut Device9,
+    stage: u32,
+    state: u32,
+    value: u32,
+) -> HRESULT {
+    result(with_device(this, |device| {
+        device.texture_stage_states.insert((stage, state), value);
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_sampler_state(
+    this: *mut Device9,
+    sampler: u32,
+    state: u32,
+    out: *mut u32,
+) -> HRESULT {
+    result(with_device(this, |device| put(out, device.sampler_states.get(&(sampler, state)).copied().unwrap_or(0))))
+}
+
+unsafe extern "system" fn device_set_sampler_state(
+    this: *mut Device9,
+    sampler: u32,
+    state: u32,
+    value: u32,
+) -> HRESULT {
+    result(with_device(this, |device| {
+        device.sampler_states.insert((sampler, state), value);
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_validate_device(this: *mut Device9, passes: *mut u32) -> HRESULT {
+    result(with_device(this, |_| put(passes, 1)))
+}
+
+unsafe extern "system" fn device_set_scissor_rect(this: *mut Device9, rect: *const Rect) -> HRESULT {
+    result(with_device(this, |device| {
+        device.scissor = get(rect)?;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_scissor_rect(this: *mut Device9, out: *mut Rect) -> HRESULT {
+    result(with_device(this, |device| put(out, device.scissor)))
+}
+
+unsafe extern "system" fn device_set_software_vertex_processing(this: *mut Device9, software: BOOL) -> HRESULT {
+    result(with_device(this, |device| {
+        // Only a mixed device may switch; vertices are processed the same
+        // way whichever it chooses
+        if device.behavior & D3DCREATE_MIXED_VERTEXPROCESSING == 0 {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        device.software_vertex_processing = software != 0;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_software_vertex_processing(this: *mut Device9) -> BOOL {
+    with_device(this, |device| Ok(device.software_vertex_processing as BOOL)).unwrap_or(0)
+}
+
+unsafe extern "system" fn device_set_npatch_mode(this: *mut Device9, segments: f32) -> HRESULT {
+    if segments > 1.0 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn device_get_npatch_mode(_this: *mut Device9) -> f32 {
+    0.0
+}
+
+unsafe extern "system" fn device_draw_primitive(
+    this: *mut Device9,
+    primitive: u32,
+    start_vertex: u32,
+    primitive_count: u32,
+) -> HRESULT {
+    let Some(count) = vertex_count(primitive, primitive_count) else {
+        return D3DERR_INVALIDCALL;
+    };
+    result(with_device(this, |device| {
+        let stream = device.stream.as_ref().ok_or(D3DERR_INVALIDCALL)?;
+        let (id, offset, stride) = (stream.buffer.id(), stream.offset, stream.stride);
+        let buffers = BUFFERS.lock();
+        let buffer = buffers.get(&id).ok_or(D3DERR_INVALIDCALL)?;
+        let source = Source { data: &buffer.data, base: offset as isize, stride: stride as usize };
+        let indices: Vec<u32> = (0..count).map(|i| start_vertex.saturating_add(i)).collect();
+        device.draw(primitive, &source, &indices)
+    }))
+}
+
+unsafe extern "system" fn device_draw_indexed_primitive(
+    this: *mut Device9,
+    primitive: u32,
+    base_vertex: i32,
+    _min_vertex: u32,
+    _vertex_count: u32,
+    start_index: u32,
+    primitive_count: u32,
+) -> HRESULT {
+    let Some(count) = vertex_count(primitive, primitive_count) else {
+        return D3DERR_INVALIDCALL;
+    };
+    result(with_device(this, |device| {
+        let stream = device.stream.as_ref().ok_or(D3DERR_INVALIDCALL)?;
+        let index_buffer = device.indices.as_ref().ok_or(D3DERR_INVALIDCALL)?.id();
+        let (id, offset, stride) = (stream.buffer.id(), stream.offset, stream.stride);
+        let buffers = BUFFERS.lock();
+        let indices = buffers.get(&index_buffer).ok_or(D3DERR_INVALIDCALL)?;
+        let format = indices.index_format.unwrap_or(D3DFMT_INDEX16);
+        let size = if format == D3DFMT_INDEX32 { 4 } else { 2 };
+        let first = (start_index as usize).checked_mul(size).ok_or(D3DERR_INVALIDCALL)?;
+        let indices = read_indices(indices.data.get(first..).ok_or(D3DERR_INVALIDCALL)?, format, count as usize)?;
+        let buffer = buffers.get(&id).ok_or(D3DERR_INVALIDCALL)?;
+        let base = offset as isize + base_vertex as isize * stride as isize;
+        let source = Source { data: &buffer.data, base, stride: stride as usize };
+        device.draw(primitive, &source, &indices)
+    }))
+}
+
+unsafe extern "system" fn device_draw_primitive_up(
+    this: *mut Device9,
+    primitive: u32,
+    primitive_count: u32,
+    vertices: *const c_void,
+    stride: u32,
+) -> HRESULT {
+    let Some(count) = vertex_count(primitive, primitive_count) else {
+        return D3DERR_INVALIDCALL;
+    };
+    if vertices.is_null() || stride == 0 {
+        return D3DERR_INVALIDCALL;
+    }
+    let data = core::slice::from_raw_parts(vertices.cast::<u8>(), count as usize * stride as usize);
+    result(with_device(this, |device| {
+        // As on Windows, drawing from memory unsets stream 0
+        device.stream = None;
+        let source = Source { data, base: 0, stride: stride as usize };
+        let indices: Vec<u32> = (0..count).collect();
+        device.draw(primitive, &source, &indices)
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe extern "system" fn device_draw_indexed_primitive_up(
+    this: *mut Device9,
+    primitive: u32,
+    min_vertex: u32,
+    vertex_count_used: u32,
+    primitive_count: u32,
+    index_data: *const c_void,
+    index_format: u32,
+    vertices: *const c_void,
+    stride: u32,
+) -> HRESULT {
+    let Some(count) = vertex_count(primitive, primitive_count) else {
+        return D3DERR_INVALIDCALL;
+    };
+    let formats = [D3DFMT_INDEX16, D3DFMT_INDEX32];
+    if index_data.is_null() || vertices.is_null() || stride == 0 || !formats.contains(&index_format) {
+        return D3DERR_INVALIDCALL;
+    }
+    let index_size = if index_format == D3DFMT_INDEX32 { 4 } else { 2 };
+    let index_data = core::slice::from_raw_parts(index_data.cast::<u8>(), count as usize * index_size);
+    let Ok(indices) = read_indices(index_data, index_format, count as usize) else {
+        return D3DERR_INVALIDCALL;
+    };
+    // The vertices the indices may reach, as the app says
+    let reach = min_vertex as usize + vertex_count_used as usize;
+    let data = core::slice::from_raw_parts(vertices.cast::<u8>(), reach * stride as usize);
+    result(with_device(this, |device| {
+        device.stream = None;
+        device.indices = None;
+        let source = Source { data, base: 0, stride: stride as usize };
+        device.draw(primitive, &source, &indices)
+    }))
+}
+
+unsafe extern "system" fn device_set_fvf(this: *mut Device9, fvf: u32) -> HRESULT {
+    result(with_device(this, |device| {
+        device.fvf = fvf;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_get_fvf(this: *mut Device9, out: *mut u32) -> HRESULT {
+    result(with_device(this, |device| put(out, device.fvf)))
+}
+
+unsafe extern "system" fn device_set_stream_source(
+    this: *mut Device9,
+    stream: u32,
+    buffer: *mut Buffer9,
+    offset: u32,
+    stride: u32,
+) -> HRESULT {
+    if stream != 0 || (!buffer.is_null() && (*buffer).index) {
+        return D3DERR_INVALIDCALL;
+    }
+    let held = Held::new(buffer);
+    // The previous source is let go after the device is, so its release
+    // does not run under the device lock
+    let previous = with_device(this, |device| {
+        let source = held.map(|buffer| StreamSource { buffer, offset, stride });
+        Ok(core::mem::replace(&mut device.stream, source))
+    });
+    let status = result(previous.as_ref().map(|_| ()).map_err(|error| *error));
+    drop(previous);
+    status
+}
+
+unsafe extern "system" fn device_get_stream_source(
+    this: *mut Device9,
+    stream: u32,
+    out: *mut *mut Buffer9,
+    offset: *mut u32,
+    stride: *mut u32,
+) -> HRESULT {
+    if stream != 0 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |device| {
+        let (buffer, source_offset, source_stride) = match &device.stream {
+            Some(source) => (source.buffer.0.as_ptr(), source.offset, source.stride),
+            None => (null_mut(), 0, 0),
+        };
+        put(out, buffer)?;
+        put(offset, source_offset)?;
+        put(stride, source_stride)?;
+        if !buffer.is_null() {
+            buffer_add_ref(buffer);
+        }
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn device_set_stream_source_freq(this: *mut Device9, stream: u32, divider: u32) -> HRESULT {
+    // No instancing: every vertex is its own
+    if stream != 0 || divider != 1 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |_| Ok(())))
+}
+
+unsafe extern "system" fn device_get_stream_source_freq(this: *mut Device9, stream: u32, out: *mut u32) -> HRESULT {
+    if stream != 0 {
+        return D3DERR_INVALIDCALL;
+    }
+    result(with_device(this, |_| put(out, 1)))
+}
+
+unsafe extern "system" fn device_set_indices(this: *mut Device9, buffer: *mut Buffer9) -> HRESULT {
+    if !buffer.is_null() && !(*buffer).index {
+        return D3DERR_INVALIDCALL;
+    }
+    let held = Held::new(buffer);
+    let previous = with_device(this, |device| Ok(core::mem::replace(&mut device.indices, held)));
+    let status = result(previous.as_ref().map(|_| ()).map_err(|error| *error));
+    drop(previous);
+    status
+}
+
+unsafe extern "system" fn device_get_indices(this: *mut Device9, out: *mut *mut Buffer9) -> HRESULT {
+    result(with_device(this, |device| {
+        let buffer = device.indices.as_ref().map_or(null_mut(), |held| held.0.as_ptr());
+        put(out, buffer)?;
+        if !buffer.is_null() {
+            buffer_add_ref(buffer);
+        }
+        Ok(())
+    }))
+}
+
+// IDirect3DVertexBuffer9 and IDirect3DIndexBuffer9
+
+unsafe extern "system" fn buffer_unavailable(_this: *mut Buffer9) -> HRESULT {
+    D3DERR_NOTAVAILABLE
+}
+
+unsafe extern "system" fn buffer_query_interface(this: *mut Buffer9, riid: REFIID, out: *mut LPVOID) -> HRESULT {
+    if this.is_null() || riid.is_null() || out.is_null() {
+        return E_POINTER;
+    }
+    let own = if (*this).index { IID_IDIRECT3DINDEXBUFFER9 } else { IID_IDIRECT3DVERTEXBUFFER9 };
+    if ![GUID::IID_IUnknown, IID_IDIRECT3DRESOURCE9, own].contains(&*riid) {
+        *out = null_mut();
+        return E_NOINTERFACE;
+    }
+    buffer_add_ref(this);
+    *out = this.cast();
+    S_OK
+}
+
+unsafe extern "system" fn buffer_add_ref(this: *mut Buffer9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    (*this).ref_count.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+unsafe extern "system" fn buffer_release(this: *mut Buffer9) -> u32 {
+    if this.is_null() {
+        return 0;
+    }
+    let count = (*this).ref_count.fetch_sub(1, Ordering::SeqCst) - 1;
+    if count == 0 {
+        BUFFERS.lock().remove(&(*this).id);
+        drop(Box::from_raw(this));
+    }
+    count
+}
+
+unsafe extern "system" fn buffer_get_device(this: *mut Buffer9, out: *mut *mut Device9) -> HRESULT {
+    if this.is_null() {
+        return E_POINTER;
+    }
+    let device = (*this).device;
+    if let Err(error) = put(out, device) {
+        return error;
+    }
+    device_add_ref(device);
+    D3D_OK
+}
+
+unsafe extern "system" fn buffer_set_priority(this: *mut Buffer9, priority: u32) -> u32 {
+    with_buffer(this, |buffer| Ok(core::mem::replace(&mut buffer.priority, priority))).unwrap_or(0)
+}
+
+unsafe extern "system" fn buffer_get_priority(this: *mut Buffer9) -> u32 {
+    with_buffer(this, |buffer| Ok(buffer.priority)).unwrap_or(0)
+}
+
+unsafe extern "system" fn buffer_preload(_this: *mut Buffer9) {}
+
+unsafe extern "system" fn buffer_get_type(this: *mut Buffer9) -> u32 {
+    if !this.is_null() && (*this).index {
+        D3DRTYPE_INDEXBUFFER
+    } else {
+        D3DRTYPE_VERTEXBUFFER
+    }
+}
+
+unsafe extern "system" fn buffer_lock(
+    this: *mut Buffer9,
+    offset: u32,
+    size: u32,
+    out: *mut *mut c_void,
+    _flags: u32,
+) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        let length = buffer.data.len();
+        let offset = offset as usize;
+        // Size 0 locks to the end
+        let size = if size == 0 { length.saturating_sub(offset) } else { size as usize };
+        if offset.checked_add(size).map_or(true, |end| end > length) {
+            return Err(D3DERR_INVALIDCALL);
+        }
+        // The data never moves, so the pointer holds after the lock is let
+        // go, as the app expects while it has the buffer locked
+        put(out, buffer.data.as_mut_ptr().add(offset).cast())?;
+        buffer.locks += 1;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_unlock(this: *mut Buffer9) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        buffer.locks = buffer.locks.checked_sub(1).ok_or(D3DERR_INVALIDCALL)?;
+        Ok(())
+    }))
+}
+
+unsafe extern "system" fn buffer_get_desc(this: *mut Buffer9, out: *mut c_void) -> HRESULT {
+    result(with_buffer(this, |buffer| {
+        let size = buffer.data.len() as u32;
+        match buffer.index_format {
+            Some(format) => put(
+                out.cast(),
+                IndexBufferDesc {
+                    format,
+                    resource_type: D3DRTYPE_INDEXBUFFER,
+                    usage: buffer.usage,
+                    pool: buffer.pool,
+                    size,
+                },
+            ),
+            None => put(
+                out.cast(),
+                VertexBufferDesc {
+                    format: D3DFMT_VERTEXDATA,
+                    resource_type: D3DRTYPE_VERTEXBUFFER,
+                    usage: buffer.usage,
+                    pool: buffer.pool,
+                    size,
+                    fvf: buffer.fvf,
+                },
+            ),
+        }
+    }))
+}
+
+/// Direct3DCreate9 - Create the object that lists the adapter and makes
+/// devices on it
+#[no_mangle]
+pub extern "C" fn Direct3DCreate9(sdk_version: u32) -> *mut Direct3D9 {
+    // The debug runtime's version has the top bit set
+    if sdk_version & 0x7FFF_FFFF != D3D_SDK_VERSION {
+        return null_mut();
+    }
+    Box::into_raw(Box::new(Direct3D9 { vtbl: &DIRECT3D_VTBL, ref_count: AtomicU32::new(1) }))
+}
+
+/// Let go of an object Direct3DCreate9 gave
+///
+/// # Safety
+/// `d3d` must be null or a live object from Direct3DCreate9.
+pub unsafe fn release(d3d: *mut Direct3D9) -> u32 {
+    d3d_release(d3d)
+}
+
+/// Draw a lit triangle through a device of `width` by `height`, as an app
+/// would, and give back the color at its middle
+pub fn self_test(width: u32, height: u32) -> Result<u32, HRESULT> {
+    #[repr(C)]
+    struct LitVertex {
+        position: [f32; 3],
+        normal: [f32; 3],
+    }
+
+    unsafe {
+        let d3d = Direct3DCreate9(D3D_SDK_VERSION);
+        if d3d.is_null() {
+            return Err(D3DERR_NOTAVAILABLE);
+        }
+        let mut params = PresentParameters {
+            back_buffer_width: width,
+            back_buffer_height: height,
+            swap_effect: D3DSWAPEFFECT_DISCARD,
+            windowed: 1,
+            enable_auto_depth_stencil: 1,
+            auto_depth_stencil_format: D3DFMT_D24S8,
+            ..PresentParameters::default()
+        };
+        let mut device = null_mut();
+        let behavior = D3DCREATE_HARDWARE_VERTEXPROCESSING;
+        let status = d3d_create_device(d3d, 0, D3DDEVTYPE_HAL, Handle::NULL, behavior, &mut params, &mut device);
+        d3d_release(d3d);
+        if status != D3D_OK {
+            return Err(status);
+        }
+
+        // Facing the camera down -z from z = 0.5, lit head on in red
+        let vertices = [
+            LitVertex { position: [-0.5, -0.5, 0.5], normal: [0.0, 0.0, -1.0] },
+            LitVertex { position: [0.0, 0.5, 0.5], normal: [0.0, 0.0, -1.0] },
+            LitVertex { position: [0.5, -0.5, 0.5], normal: [0.0, 0.0, -1.0] },
+        ];
+        let material = Material { diffuse: ColorValue { r: 1.0, g: 0.0, b: 0.0, a: 1.0 }, ..Material::default() };
+        let mut status = device_set_material(device, &material);
+        if status == D3D_OK {
+            status = device_light_enable(device, 0, 1);
+        }
+        let fvf = D3DFVF_XYZ | D3DFVF_NORMAL;
+        let mut buffer = null_mut();
+        if status == D3D_OK {
+            let length = core::mem::size_of_val(&vertices) as u32;
+            status = device_create_vertex_buffer(device, length, 0, fvf, D3DPOOL_MANAGED, &mut buffer, null_mut());
+        }
+        if status == D3D_OK {
+            let mut data = null_mut();
+            status = buffer_lock(buffer, 0, 0, &mut data, 0);
+            if status == D3D_OK {
+                core::ptr::copy_nonoverlapping(vertices.as_ptr(), data.cast(), vertices.len());
+                status = buffer_unlock(buffer);
+            }
+        }
+        let steps: [&dyn Fn() -> HRESULT; 7] = [
+            &|| device_clear(device, 0, core::ptr::null(), D3DCLEAR_TARGET | D3DCLEAR_ZBUFFER, 0xFF00_0000, 1.0, 0),
+            &|| device_begin_scene(device),
+            &|| device_set_fvf(device, fvf),
+            &|| device_set_stream_source(device, 0, buffer, 0, core::mem::size_of::<LitVertex>() as u32),
+            &|| device_draw_primitive(device, D3DPT_TRIANGLELIST, 0, 1),
+            &|| device_end_scene(device),
+            &|| device_present(device, core::ptr::null(), core::ptr::null(), Handle::NULL, core::ptr::null()),
+        ];
+        for step in steps {
+            if status != D3D_OK {
+                break;
+            }
+            status = step();
+        }
+        let center = with_device(device, |device| {
+            let target = &device.target;
+            Ok(target.color[target.height / 2 * target.width + target.width / 2])
+        });
+        buffer_release(buffer);
+        device_release(device);
+        if status != D3D_OK {
+            return Err(status);
+        }
+        center
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: [f32; 4]) -> Processed {
+        Processed { position, color: [1.0; 4] }
+    }
+
+    #[test]
+    fn test_vertex_count() {
+        assert_eq!(vertex_count(D3DPT_POINTLIST, 4), Some(4));
+        assert_eq!(vertex_count(D3DPT_LINELIST, 4), Some(8));
+        assert_eq!(vertex_count(D3DPT_LINESTRIP, 4), Some(5));
+        assert_eq!(vertex_count(D3DPT_TRIANGLELIST, 4), Some(12));
+        assert_eq!(vertex_count(D3DPT_TRIANGLESTRIP, 4), Some(6));
+        assert_eq!(vertex_count(D3DPT_TRIANGLEFAN, 4), Some(6));
+        assert_eq!(vertex_count(D3DPT_TRIANGLELIST, u32::MAX), None);
+        assert_eq!(vertex_count(0, 1), None);
+    }
+
+    #[test]
+    fn test_read_indices() {
+        let data = [1, 0, 2, 0, 3, 0, 4, 0];
+        assert_eq!(read_indices(&data, D3DFMT_INDEX16, 4), Ok(vec![1, 2, 3, 4]));
+        assert_eq!(read_indices(&data, D3DFMT_INDEX32, 2), Ok(vec![0x0002_0001, 0x0004_0003]));
+        assert_eq!(read_indices(&data, D3DFMT_INDEX32, 3), Err(D3DERR_INVALIDCALL));
+        assert_eq!(read_indices(&data, D3DFMT_INDEX16, usize::MAX), Err(D3DERR_INVALIDCALL));
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::of(D3DFVF_XYZ | D3DFVF_NORMAL | D3DFVF_DIFFUSE).unwrap();
+        assert!(!layout.pretransformed);
+        assert_eq!(layout.normal, Some(12));
+        assert_eq!(layout.diffuse, Some(24));
+        assert_eq!(layout.specular, None);
+        assert_eq!(layout.size, 28);
+
+        let layout = Layout::of(D3DFVF_XYZRHW | D3DFVF_DIFFUSE | D3DFVF_SPECULAR).unwrap();
+        assert!(layout.pretransformed);
+        assert_eq!(layout.diffuse, Some(16));
+        assert_eq!(layout.specular, Some(20));
+        assert_eq!(layout.size, 24);
+
+        assert!(Layout::of(0).is_none());
+    }
+
+    #[test]
+    fn test_clip_polygon() {
+        // Inside every plane, so left alone
+        let inside = vec![vertex([0.0, 0.0, 0.5, 1.0]), vertex([1.0, 0.0, 0.5, 1.0]), vertex([0.0, 1.0, 0.5, 1.0])];
+        assert_eq!(clip_polygon(inside).len(), 3);
+
+        // Wholly behind the near plane
+        let behind = vec![vertex([0.0, 0.0, -1.0, 1.0]), vertex([1.0, 0.0, -1.0, 1.0]), vertex([0.0, 1.0, -1.0, 1.0])];
+        assert!(clip_polygon(behind).is_empty());
+
+        // One corner past the near plane turns the triangle into a quad
+        let crossing = vec![vertex([0.0, 0.0, -0.5, 1.0]), vertex([1.0, 0.0, 0.5, 1.0]), vertex([0.0, 1.0, 0.5, 1.0])];
+        let clipped = clip_polygon(crossing);
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|v| v.position[2] >= -1e-6));
+    }
+
+    #[test]
+    fn test_clip_line() {
+        let (a, b) = clip_line(vertex([0.0, 0.0, -1.0, 1.0]), vertex([0.0, 0.0, 1.0, 1.0])).unwrap();
+        assert!((a.position[2] - 0.0).abs() < 1e-6);
+        assert!((b.position[2] - 1.0).abs() < 1e-6);
+        assert!(clip_line(vertex([0.0, 0.0, 2.0, 1.0]), vertex([0.0, 0.0, 3.0, 1.0])).is_none());
+    }
+
+    #[test]
+    fn test_present_parameters_default() {
+        let params = PresentParameters::default();
+        assert_eq!(params.device_window, Handle::NULL);
+        assert_eq!(params.back_buffer_width, 0);
+    }
+}
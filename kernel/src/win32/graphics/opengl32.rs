@@ -0,0 +1,2363 @@
+//! OpenGL
+//!
+//! What opengl32.dll exports: the WGL calls that make contexts for device
+//! contexts, the pixel format calls gdi32 forwards here, and the OpenGL 1.1
+//! API. No driver offers a GL engine, so every context draws with the
+//! software rasterizer in `gpu::opengl::soft` into a back buffer, and
+//! SwapBuffers shows it in a desktop window with the Win32 window's size
+//! and title.
+//!
+//! The fixed-function pipeline is there up to the rasterizer: the
+//! modelview, projection and texture matrix stacks, immediate mode and
+//! client vertex arrays, near and far clipping, culling, flat and smooth
+//! shading, 2D textures with their environment, the alpha and depth tests,
+//! blending and the scissor. Lighting, fog, stencil, accumulation, display
+//! lists, evaluators and feedback are not; nor are mipmaps past the base
+//! level, which is always what is sampled. GL_VERSION says 1.1, and
+//! wglGetProcAddress finds nothing, as there are no extensions.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::ptr::null;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::gpu::opengl::soft::{self, Combine, Pipeline, Texture, Wrap};
+use crate::gpu::opengl::{
+    GLbitfield, GLboolean, GLclampd, GLclampf, GLdouble, GLenum, GLfloat, GLint, GLsizei, GLubyte, GLuint, GL_BLEND,
+    GL_BYTE, GL_COLOR_BUFFER_BIT, GL_CULL_FACE, GL_DEPTH_BUFFER_BIT, GL_DEPTH_COMPONENT, GL_DEPTH_TEST, GL_DOUBLE,
+    GL_FALSE, GL_FLOAT, GL_INT, GL_INVALID_ENUM, GL_INVALID_OPERATION, GL_INVALID_VALUE, GL_LINES, GL_LINE_LOOP,
+    GL_LINE_STRIP, GL_NO_ERROR, GL_ONE, GL_ONE_MINUS_SRC_ALPHA, GL_POINTS, GL_RGB, GL_RGB8, GL_RGBA, GL_RGBA8,
+    GL_SCISSOR_TEST, GL_SHORT, GL_SRC_ALPHA, GL_STENCIL_BUFFER_BIT, GL_TEXTURE_2D, GL_TRIANGLES, GL_TRIANGLE_FAN,
+    GL_TRIANGLE_STRIP, GL_TRUE, GL_UNSIGNED_BYTE, GL_UNSIGNED_INT, GL_UNSIGNED_SHORT, GL_ZERO,
+};
+use crate::gpu::raster::{self, RenderTarget};
+use crate::graphics::window::WindowId;
+use crate::win32::gdi::GDI_MANAGER;
+use crate::win32::{Handle, BOOL};
+
+use super::surface;
+
+pub const GL_QUADS: GLenum = 0x0007;
+pub const GL_QUAD_STRIP: GLenum = 0x0008;
+pub const GL_POLYGON: GLenum = 0x0009;
+
+pub const GL_ADD: GLenum = 0x0104;
+
+pub const GL_NEVER: GLenum = 0x0200;
+pub const GL_LESS: GLenum = 0x0201;
+pub const GL_LEQUAL: GLenum = 0x0203;
+pub const GL_ALWAYS: GLenum = 0x0207;
+
+pub const GL_SRC_COLOR: GLenum = 0x0300;
+pub const GL_ONE_MINUS_SRC_COLOR: GLenum = 0x0301;
+pub const GL_DST_ALPHA: GLenum = 0x0304;
+pub const GL_ONE_MINUS_DST_ALPHA: GLenum = 0x0305;
+pub const GL_DST_COLOR: GLenum = 0x0306;
+pub const GL_ONE_MINUS_DST_COLOR: GLenum = 0x0307;
+
+pub const GL_FRONT: GLenum = 0x0404;
+pub const GL_BACK: GLenum = 0x0405;
+pub const GL_FRONT_AND_BACK: GLenum = 0x0408;
+
+pub const GL_STACK_OVERFLOW: GLenum = 0x0503;
+pub const GL_STACK_UNDERFLOW: GLenum = 0x0504;
+
+pub const GL_CW: GLenum = 0x0900;
+pub const GL_CCW: GLenum = 0x0901;
+
+pub const GL_CURRENT_COLOR: GLenum = 0x0B00;
+pub const GL_POLYGON_MODE: GLenum = 0x0B40;
+pub const GL_CULL_FACE_MODE: GLenum = 0x0B45;
+pub const GL_FRONT_FACE: GLenum = 0x0B46;
+pub const GL_LIGHTING: GLenum = 0x0B50;
+pub const GL_SHADE_MODEL: GLenum = 0x0B54;
+pub const GL_DEPTH_RANGE: GLenum = 0x0B70;
+pub const GL_DEPTH_WRITEMASK: GLenum = 0x0B72;
+pub const GL_DEPTH_CLEAR_VALUE: GLenum = 0x0B73;
+pub const GL_DEPTH_FUNC: GLenum = 0x0B74;
+pub const GL_MATRIX_MODE: GLenum = 0x0BA0;
+pub const GL_VIEWPORT: GLenum = 0x0BA2;
+pub const GL_MODELVIEW_STACK_DEPTH: GLenum = 0x0BA3;
+pub const GL_PROJECTION_STACK_DEPTH: GLenum = 0x0BA4;
+pub const GL_TEXTURE_STACK_DEPTH: GLenum = 0x0BA5;
+pub const GL_MODELVIEW_MATRIX: GLenum = 0x0BA6;
+pub const GL_PROJECTION_MATRIX: GLenum = 0x0BA7;
+pub const GL_TEXTURE_MATRIX: GLenum = 0x0BA8;
+pub const GL_ALPHA_TEST: GLenum = 0x0BC0;
+pub const GL_ALPHA_TEST_FUNC: GLenum = 0x0BC1;
+pub const GL_ALPHA_TEST_REF: GLenum = 0x0BC2;
+pub const GL_DITHER: GLenum = 0x0BD0;
+pub const GL_BLEND_DST: GLenum = 0x0BE0;
+pub const GL_BLEND_SRC: GLenum = 0x0BE1;
+pub const GL_SCISSOR_BOX: GLenum = 0x0C10;
+pub const GL_COLOR_CLEAR_VALUE: GLenum = 0x0C22;
+pub const GL_DOUBLEBUFFER: GLenum = 0x0C32;
+pub const GL_UNPACK_ALIGNMENT: GLenum = 0x0CF5;
+pub const GL_PACK_ALIGNMENT: GLenum = 0x0D05;
+pub const GL_MAX_TEXTURE_SIZE: GLenum = 0x0D33;
+pub const GL_MAX_MODELVIEW_STACK_DEPTH: GLenum = 0x0D36;
+pub const GL_MAX_PROJECTION_STACK_DEPTH: GLenum = 0x0D38;
+pub const GL_MAX_TEXTURE_STACK_DEPTH: GLenum = 0x0D39;
+pub const GL_MAX_VIEWPORT_DIMS: GLenum = 0x0D3A;
+pub const GL_RED_BITS: GLenum = 0x0D52;
+pub const GL_GREEN_BITS: GLenum = 0x0D53;
+pub const GL_BLUE_BITS: GLenum = 0x0D54;
+pub const GL_ALPHA_BITS: GLenum = 0x0D55;
+pub const GL_DEPTH_BITS: GLenum = 0x0D56;
+pub const GL_STENCIL_BITS: GLenum = 0x0D57;
+
+pub const GL_ALPHA: GLenum = 0x1906;
+pub const GL_LUMINANCE: GLenum = 0x1909;
+pub const GL_LUMINANCE_ALPHA: GLenum = 0x190A;
+
+pub const GL_POINT: GLenum = 0x1B00;
+pub const GL_LINE: GLenum = 0x1B01;
+pub const GL_FILL: GLenum = 0x1B02;
+
+pub const GL_FLAT: GLenum = 0x1D00;
+pub const GL_SMOOTH: GLenum = 0x1D01;
+
+pub const GL_REPLACE: GLenum = 0x1E01;
+
+pub const GL_VENDOR: GLenum = 0x1F00;
+pub const GL_RENDERER: GLenum = 0x1F01;
+pub const GL_VERSION: GLenum = 0x1F02;
+pub const GL_EXTENSIONS: GLenum = 0x1F03;
+
+pub const GL_MODELVIEW: GLenum = 0x1700;
+pub const GL_PROJECTION: GLenum = 0x1701;
+pub const GL_TEXTURE: GLenum = 0x1702;
+
+pub const GL_MODULATE: GLenum = 0x2100;
+pub const GL_DECAL: GLenum = 0x2101;
+pub const GL_TEXTURE_ENV_MODE: GLenum = 0x2200;
+pub const GL_TEXTURE_ENV_COLOR: GLenum = 0x2201;
+pub const GL_TEXTURE_ENV: GLenum = 0x2300;
+
+pub const GL_NEAREST: GLenum = 0x2600;
+pub const GL_LINEAR: GLenum = 0x2601;
+pub const GL_NEAREST_MIPMAP_NEAREST: GLenum = 0x2700;
+pub const GL_LINEAR_MIPMAP_NEAREST: GLenum = 0x2701;
+pub const GL_NEAREST_MIPMAP_LINEAR: GLenum = 0x2702;
+pub const GL_LINEAR_MIPMAP_LINEAR: GLenum = 0x2703;
+pub const GL_TEXTURE_MAG_FILTER: GLenum = 0x2800;
+pub const GL_TEXTURE_MIN_FILTER: GLenum = 0x2801;
+pub const GL_TEXTURE_WRAP_S: GLenum = 0x2802;
+pub const GL_TEXTURE_WRAP_T: GLenum = 0x2803;
+pub const GL_CLAMP: GLenum = 0x2900;
+pub const GL_REPEAT: GLenum = 0x2901;
+
+pub const GL_TEXTURE_BINDING_2D: GLenum = 0x8069;
+pub const GL_VERTEX_ARRAY: GLenum = 0x8074;
+pub const GL_NORMAL_ARRAY: GLenum = 0x8075;
+pub const GL_COLOR_ARRAY: GLenum = 0x8076;
+pub const GL_TEXTURE_COORD_ARRAY: GLenum = 0x8078;
+pub const GL_BGR: GLenum = 0x80E0;
+pub const GL_BGRA: GLenum = 0x80E1;
+pub const GL_CLAMP_TO_EDGE: GLenum = 0x812F;
+
+// Pixel format descriptor flags
+pub const PFD_DOUBLEBUFFER: u32 = 0x0000_0001;
+pub const PFD_DRAW_TO_WINDOW: u32 = 0x0000_0004;
+pub const PFD_SUPPORT_OPENGL: u32 = 0x0000_0020;
+pub const PFD_GENERIC_FORMAT: u32 = 0x0000_0040;
+pub const PFD_TYPE_RGBA: u8 = 0;
+pub const PFD_MAIN_PLANE: u8 = 0;
+
+// The one pixel format: 32-bit color, 24-bit depth, double-buffered
+const PIXEL_FORMAT: i32 = 1;
+const PIXEL_FORMAT_COUNT: i32 = 1;
+
+const MAX_TEXTURE_SIZE: i32 = 2048;
+// Stack depths GL promises at least
+const MAX_MODELVIEW_DEPTH: usize = 32;
+const MAX_PROJECTION_DEPTH: usize = 2;
+const MAX_TEXTURE_DEPTH: usize = 2;
+
+// A back buffer's size when the window gives none
+const DEFAULT_SIZE: (u32, u32) = (640, 480);
+
+static VENDOR: &[u8] = b"Rust OS Graphics\0";
+static RENDERER: &[u8] = b"Software OpenGL Renderer\0";
+static VERSION: &[u8] = b"1.1.0\0";
+static EXTENSIONS: &[u8] = b"GL_EXT_bgra\0";
+
+/// PIXELFORMATDESCRIPTOR
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelFormatDescriptor {
+    pub size: u16,
+    pub version: u16,
+    pub flags: u32,
+    pub pixel_type: u8,
+    pub color_bits: u8,
+    pub red_bits: u8,
+    pub red_shift: u8,
+    pub green_bits: u8,
+    pub green_shift: u8,
+    pub blue_bits: u8,
+    pub blue_shift: u8,
+    pub alpha_bits: u8,
+    pub alpha_shift: u8,
+    pub accum_bits: u8,
+    pub accum_red_bits: u8,
+    pub accum_green_bits: u8,
+    pub accum_blue_bits: u8,
+    pub accum_alpha_bits: u8,
+    pub depth_bits: u8,
+    pub stencil_bits: u8,
+    pub aux_buffers: u8,
+    pub layer_type: u8,
+    pub reserved: u8,
+    pub layer_mask: u32,
+    pub visible_mask: u32,
+    pub damage_mask: u32,
+}
+
+fn pixel_format_descriptor() -> PixelFormatDescriptor {
+    PixelFormatDescriptor {
+        size: core::mem::size_of::<PixelFormatDescriptor>() as u16,
+        version: 1,
+        flags: PFD_DRAW_TO_WINDOW | PFD_SUPPORT_OPENGL | PFD_DOUBLEBUFFER | PFD_GENERIC_FORMAT,
+        pixel_type: PFD_TYPE_RGBA,
+        color_bits: 32,
+        red_bits: 8,
+        red_shift: 16,
+        green_bits: 8,
+        green_shift: 8,
+        blue_bits: 8,
+        blue_shift: 0,
+        alpha_bits: 8,
+        alpha_shift: 24,
+        depth_bits: 24,
+        layer_type: PFD_MAIN_PLANE,
+        ..PixelFormatDescriptor::default()
+    }
+}
+
+// Column-major, as GL has them
+type Matrix = [f32; 16];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    core::array::from_fn(|i| {
+        let (column, row) = (i / 4, i % 4);
+        (0..4).map(|k| a[k * 4 + row] * b[column * 4 + k]).sum()
+    })
+}
+
+fn transform(m: &Matrix, v: [f32; 4]) -> [f32; 4] {
+    core::array::from_fn(|row| (0..4).map(|k| m[k * 4 + row] * v[k]).sum())
+}
+
+const PI: f32 = core::f32::consts::PI;
+
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut guess = if x > 1.0 { x / 2.0 } else { 1.0 };
+    for _ in 0..24 {
+        guess = (guess + x / guess) * 0.5;
+    }
+    guess
+}
+
+// Taylor series after folding into -pi/2 to pi/2
+fn sin(x: f32) -> f32 {
+    let turns = x / (2.0 * PI);
+    let whole = turns as i32 as f32 - if turns < 0.0 { 1.0 } else { 0.0 };
+    let mut x = x - whole * 2.0 * PI;
+    if x > PI {
+        x -= 2.0 * PI;
+    }
+    if x > PI / 2.0 {
+        x = PI - x;
+    } else if x < -PI / 2.0 {
+        x = -PI - x;
+    }
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0 * (1.0 - x2 / 110.0)))))
+}
+
+fn cos(x: f32) -> f32 {
+    sin(x + PI / 2.0)
+}
+
+fn rotation(degrees: f32, x: f32, y: f32, z: f32) -> Matrix {
+    let length = sqrt(x * x + y * y + z * z);
+    if length == 0.0 {
+        return IDENTITY;
+    }
+    let (x, y, z) = (x / length, y / length, z / length);
+    let radians = degrees * PI / 180.0;
+    let (c, s) = (cos(radians), sin(radians));
+    let d = 1.0 - c;
+    [
+        x * x * d + c,
+        y * x * d + z * s,
+        x * z * d - y * s,
+        0.0,
+        x * y * d - z * s,
+        y * y * d + c,
+        y * z * d + x * s,
+        0.0,
+        x * z * d + y * s,
+        y * z * d - x * s,
+        z * z * d + c,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+    ]
+}
+
+fn translation(x: f32, y: f32, z: f32) -> Matrix {
+    let mut m = IDENTITY;
+    m[12..15].copy_from_slice(&[x, y, z]);
+    m
+}
+
+fn scaling(x: f32, y: f32, z: f32) -> Matrix {
+    let mut m = IDENTITY;
+    (m[0], m[5], m[10]) = (x, y, z);
+    m
+}
+
+// A vertex as the app gave it
+#[derive(Debug, Clone, Copy)]
+struct Input {
+    position: [f32; 4],
+    // [r, g, b, a]
+    color: [f32; 4],
+    tex_coord: [f32; 4],
+}
+
+// A vertex in clip space, colored as the rasterizer wants
+#[derive(Debug, Clone, Copy)]
+struct Clipped {
+    position: [f32; 4],
+    // [a, r, g, b]
+    color: [f32; 4],
+    s: f32,
+    t: f32,
+}
+
+impl Clipped {
+    fn lerp(&self, other: &Clipped, t: f32) -> Clipped {
+        Clipped {
+            position: core::array::from_fn(|i| self.position[i] + (other.position[i] - self.position[i]) * t),
+            color: core::array::from_fn(|i| self.color[i] + (other.color[i] - self.color[i]) * t),
+            s: self.s + (other.s - self.s) * t,
+            t: self.t + (other.t - self.t) * t,
+        }
+    }
+
+    // Distances inside the near and far planes, and in front of the eye
+    fn distances(&self) -> [f32; 3] {
+        let [_, _, z, w] = self.position;
+        [w + z, w - z, w - 1e-5]
+    }
+}
+
+fn clip_polygon(mut polygon: Vec<Clipped>) -> Vec<Clipped> {
+    for plane in 0..3 {
+        if polygon.is_empty() {
+            break;
+        }
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, current) in polygon.iter().enumerate() {
+            let next = &polygon[(i + 1) % polygon.len()];
+            let (a, b) = (current.distances()[plane], next.distances()[plane]);
+            if a >= 0.0 {
+                clipped.push(*current);
+            }
+            if (a >= 0.0) != (b >= 0.0) {
+                clipped.push(current.lerp(next, a / (a - b)));
+            }
+        }
+        polygon = clipped;
+    }
+    polygon
+}
+
+fn clip_line(mut a: Clipped, mut b: Clipped) -> Option<(Clipped, Clipped)> {
+    for plane in 0..3 {
+        let (da, db) = (a.distances()[plane], b.distances()[plane]);
+        match (da >= 0.0, db >= 0.0) {
+            (true, true) => {}
+            (false, false) => return None,
+            (true, false) => b = a.lerp(&b, da / (da - db)),
+            (false, true) => a = a.lerp(&b, da / (da - db)),
+        }
+    }
+    Some((a, b))
+}
+
+// An array glVertexPointer and the like describe, in the app's memory
+#[derive(Debug, Clone, Copy, Default)]
+struct Pointer {
+    enabled: bool,
+    size: i32,
+    kind: GLenum,
+    stride: i32,
+    address: usize,
+}
+
+impl Pointer {
+    fn element_size(&self) -> usize {
+        let component = match self.kind {
+            GL_BYTE | GL_UNSIGNED_BYTE => 1,
+            GL_SHORT | GL_UNSIGNED_SHORT => 2,
+            GL_DOUBLE => 8,
+            _ => 4,
+        };
+        component * self.size as usize
+    }
+
+    // Element `index`, missing components filled from `fill`; integer
+    // colors are scaled to 0 to 1 when `normalized`
+    unsafe fn read(&self, index: usize, fill: [f32; 4], normalized: bool) -> [f32; 4] {
+        let stride = if self.stride > 0 { self.stride as usize } else { self.element_size() };
+        let base = (self.address + index * stride) as *const u8;
+        let mut out = fill;
+        for (i, value) in out.iter_mut().enumerate().take(self.size as usize) {
+            *value = match self.kind {
+                GL_BYTE => base.add(i).cast::<i8>().read() as f32 / if normalized { 127.0 } else { 1.0 },
+                GL_UNSIGNED_BYTE => base.add(i).read() as f32 / if normalized { 255.0 } else { 1.0 },
+                GL_SHORT => {
+                    base.add(2 * i).cast::<i16>().read_unaligned() as f32 / if normalized { 32767.0 } else { 1.0 }
+                }
+                GL_UNSIGNED_SHORT => {
+                    base.add(2 * i).cast::<u16>().read_unaligned() as f32 / if normalized { 65535.0 } else { 1.0 }
+                }
+                GL_INT => base.add(4 * i).cast::<i32>().read_unaligned() as f32,
+                GL_UNSIGNED_INT => base.add(4 * i).cast::<u32>().read_unaligned() as f32,
+                GL_DOUBLE => base.add(8 * i).cast::<f64>().read_unaligned() as f32,
+                _ => base.add(4 * i).cast::<f32>().read_unaligned(),
+            };
+        }
+        out
+    }
+}
+
+struct Context {
+    // The device context last made current with, and its window
+    hdc: Handle,
+    hwnd: Handle,
+    window: Option<WindowId>,
+    target: RenderTarget,
+    // The thread it is current on
+    thread: Option<u32>,
+    // Whether it has been current yet, when the viewport takes the window's
+    // size
+    sized: bool,
+
+    error: GLenum,
+    enabled: BTreeSet<GLenum>,
+    clear_color: [f32; 4],
+    clear_depth: f32,
+    viewport: [i32; 4],
+    depth_range: (f32, f32),
+    scissor: [i32; 4],
+    depth_func: GLenum,
+    depth_mask: bool,
+    blend_func: (GLenum, GLenum),
+    alpha_func: (GLenum, f32),
+    cull_face: GLenum,
+    front_face: GLenum,
+    shade_model: GLenum,
+    // For front and back faces
+    polygon_mode: [GLenum; 2],
+
+    matrix_mode: GLenum,
+    // Never empty; the last is current
+    modelview: Vec<Matrix>,
+    projection: Vec<Matrix>,
+    texture_matrix: Vec<Matrix>,
+
+    color: [f32; 4],
+    tex_coord: [f32; 4],
+    normal: [f32; 3],
+    begun: Option<GLenum>,
+    inputs: Vec<Input>,
+
+    vertex_array: Pointer,
+    color_array: Pointer,
+    tex_coord_array: Pointer,
+    normal_array: Pointer,
+
+    // Name 0 is the default texture
+    textures: BTreeMap<GLuint, Texture>,
+    next_texture: GLuint,
+    bound_texture: GLuint,
+    env_mode: GLenum,
+    env_color: [f32; 4],
+    unpack_alignment: i32,
+    pack_alignment: i32,
+}
+
+impl Context {
+    fn new(hdc: Handle, hwnd: Handle, window: Option<WindowId>, width: u32, height: u32) -> Self {
+        let mut textures = BTreeMap::new();
+        textures.insert(0, Texture::new());
+        Context {
+            hdc,
+            hwnd,
+            window,
+            target: RenderTarget::new(width as usize, height as usize),
+            thread: None,
+            sized: false,
+            error: GL_NO_ERROR,
+            enabled: [GL_DITHER].into_iter().collect(),
+            clear_color: [0.0; 4],
+            clear_depth: 1.0,
+            viewport: [0, 0, width as i32, height as i32],
+            depth_range: (0.0, 1.0),
+            scissor: [0, 0, width as i32, height as i32],
+            depth_func: GL_LESS,
+            depth_mask: true,
+            blend_func: (GL_ONE, GL_ZERO),
+            alpha_func: (GL_ALWAYS, 0.0),
+            cull_face: GL_BACK,
+            front_face: GL_CCW,
+            shade_model: GL_SMOOTH,
+            polygon_mode: [GL_FILL; 2],
+            matrix_mode: GL_MODELVIEW,
+            modelview: vec![IDENTITY],
+            projection: vec![IDENTITY],
+            texture_matrix: vec![IDENTITY],
+            color: [1.0; 4],
+            tex_coord: [0.0, 0.0, 0.0, 1.0],
+            normal: [0.0, 0.0, 1.0],
+            begun: None,
+            inputs: Vec::new(),
+            vertex_array: Pointer::default(),
+            color_array: Pointer::default(),
+            tex_coord_array: Pointer::default(),
+            normal_array: Pointer::default(),
+            textures,
+            next_texture: 1,
+            bound_texture: 0,
+            env_mode: GL_MODULATE,
+            env_color: [0.0; 4],
+            unpack_alignment: 4,
+            pack_alignment: 4,
+        }
+    }
+
+    // Keep the first error until glGetError takes it
+    fn fail(&mut self, error: GLenum) {
+        if self.error == GL_NO_ERROR {
+            self.error = error;
+        }
+    }
+
+    fn is_enabled(&self, cap: GLenum) -> bool {
+        self.enabled.contains(&cap)
+    }
+
+    fn stack(&mut self) -> (&mut Vec<Matrix>, usize) {
+        match self.matrix_mode {
+            GL_PROJECTION => (&mut self.projection, MAX_PROJECTION_DEPTH),
+            GL_TEXTURE => (&mut self.texture_matrix, MAX_TEXTURE_DEPTH),
+            _ => (&mut self.modelview, MAX_MODELVIEW_DEPTH),
+        }
+    }
+
+    fn current_matrix(&mut self) -> &mut Matrix {
+        let (stack, _) = self.stack();
+        stack.last_mut().expect("matrix stacks are never empty")
+    }
+
+    fn multiply_current(&mut self, by: &Matrix) {
+        let current = self.current_matrix();
+        *current = multiply(current, by);
+    }
+
+    // Follow the window's client area, as the default framebuffer does;
+    // what was drawn is lost when it changes
+    fn fit_window(&mut self) {
+        let (width, height) = surface::client_size(self.hwnd).unwrap_or(DEFAULT_SIZE);
+        if (width as usize, height as usize) != (self.target.width, self.target.height) {
+            self.target = RenderTarget::new(width as usize, height as usize);
+            if let Some(id) = self.window {
+                surface::resize(id, width, height);
+            }
+        }
+    }
+
+    // Rows of the back buffer are top down; GL's window coordinates are
+    // bottom up
+    fn scissor_rect(&self) -> (usize, usize, usize, usize) {
+        let (width, height) = (self.target.width as i32, self.target.height as i32);
+        if !self.is_enabled(GL_SCISSOR_TEST) {
+            return (0, 0, width as usize, height as usize);
+        }
+        let [x, y, w, h] = self.scissor;
+        let x0 = x.clamp(0, width);
+        let x1 = (x + w).clamp(x0, width);
+        let y0 = (height - (y + h)).clamp(0, height);
+        let y1 = (height - y).clamp(y0, height);
+        (x0 as usize, y0 as usize, x1 as usize, y1 as usize)
+    }
+
+    fn clear(&mut self, mask: GLbitfield) -> Result<(), GLenum> {
+        if mask & !(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT | GL_STENCIL_BUFFER_BIT) != 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        let (x0, y0, x1, y1) = self.scissor_rect();
+        let [r, g, b, a] = self.clear_color;
+        let color = raster::pack([a, r, g, b]);
+        for y in y0..y1 {
+            let row = y * self.target.width;
+            if mask & GL_COLOR_BUFFER_BIT != 0 {
+                self.target.color[row + x0..row + x1].fill(color);
+            }
+            if mask & GL_DEPTH_BUFFER_BIT != 0 {
+                self.target.depth[row + x0..row + x1].fill(self.clear_depth);
+            }
+        }
+        Ok(())
+    }
+
+    // Clip space to window coordinates, y down
+    fn to_window(&self, v: &Clipped) -> soft::Vertex {
+        let [x, y, z, w] = v.position;
+        let [vx, vy, vw, vh] = self.viewport;
+        let (near, far) = self.depth_range;
+        let window_y = vy as f32 + (y / w + 1.0) * vh as f32 / 2.0;
+        soft::Vertex {
+            x: vx as f32 + (x / w + 1.0) * vw as f32 / 2.0,
+            y: self.target.height as f32 - window_y,
+            z: near + (z / w + 1.0) / 2.0 * (far - near),
+            inv_w: 1.0 / w,
+            color: v.color,
+            s: v.s,
+            t: v.t,
+        }
+    }
+
+    fn process(&self, input: &Input, model_view_projection: &Matrix) -> Clipped {
+        let texture = transform(self.texture_matrix.last().unwrap_or(&IDENTITY), input.tex_coord);
+        let q = if texture[3] == 0.0 { 1.0 } else { texture[3] };
+        let [r, g, b, a] = input.color.map(|c| c.clamp(0.0, 1.0));
+        Clipped {
+            position: transform(model_view_projection, input.position),
+            color: [a, r, g, b],
+            s: texture[0] / q,
+            t: texture[1] / q,
+        }
+    }
+
+    /// Draw the vertices as `mode` says
+    fn draw(&mut self, mode: GLenum, inputs: &[Input]) {
+        let modelview = self.modelview.last().unwrap_or(&IDENTITY);
+        let model_view_projection = multiply(self.projection.last().unwrap_or(&IDENTITY), modelview);
+        let vertices: Vec<Clipped> = inputs.iter().map(|input| self.process(input, &model_view_projection)).collect();
+        let n = vertices.len();
+
+        // Each primitive's vertices, and the one whose color a flat one
+        // takes
+        let mut triangles: Vec<([usize; 3], usize)> = Vec::new();
+        let mut lines: Vec<([usize; 2], usize)> = Vec::new();
+        let mut points: Vec<usize> = Vec::new();
+        match mode {
+            GL_POINTS => points.extend(0..n),
+            GL_LINES => lines.extend((0..n / 2).map(|i| ([2 * i, 2 * i + 1], 2 * i + 1))),
+            GL_LINE_STRIP => lines.extend((1..n).map(|i| ([i - 1, i], i))),
+            GL_LINE_LOOP => {
+                lines.extend((1..n).map(|i| ([i - 1, i], i)));
+                if n > 1 {
+                    lines.push(([n - 1, 0], 0));
+                }
+            }
+            GL_TRIANGLES => triangles.extend((0..n / 3).map(|i| ([3 * i, 3 * i + 1, 3 * i + 2], 3 * i + 2))),
+            GL_TRIANGLE_STRIP => {
+                triangles.extend((2..n).map(
+                    |i| {
+                        if i % 2 == 0 {
+                            ([i - 2, i - 1, i], i)
+                        } else {
+                            ([i - 1, i - 2, i], i)
+                        }
+                    },
+                ))
+            }
+            GL_TRIANGLE_FAN => triangles.extend((2..n).map(|i| ([0, i - 1, i], i))),
+            GL_POLYGON => triangles.extend((2..n).map(|i| ([0, i - 1, i], 0))),
+            GL_QUADS => {
+                for quad in 0..n / 4 {
+                    let [a, b, c, d] = [0, 1, 2, 3].map(|k| 4 * quad + k);
+                    triangles.extend([([a, b, c], d), ([a, c, d], d)]);
+                }
+            }
+            GL_QUAD_STRIP => {
+                for quad in 0..n.saturating_sub(2) / 2 {
+                    let [a, b, c, d] = [0, 1, 3, 2].map(|k| 2 * quad + k);
+                    triangles.extend([([a, b, c], c), ([a, c, d], c)]);
+                }
+            }
+            _ => {}
+        }
+
+        let flat = self.shade_model == GL_FLAT;
+        let texture = bound_texture(
+            &self.textures,
+            self.bound_texture,
+            self.is_enabled(GL_TEXTURE_2D),
+            self.env_mode,
+            self.env_color,
+        );
+        let pipeline = Pipeline {
+            state: self.raster_state(),
+            scissor: self.scissor_rect(),
+            texture,
+            alpha_test: self.is_enabled(GL_ALPHA_TEST).then(|| (self.alpha_func.0 - GL_NEVER + 1, self.alpha_func.1)),
+        };
+        let mut target = core::mem::replace(&mut self.target, RenderTarget::new(0, 0));
+
+        for (corners, provoking) in triangles {
+            let mut corners = corners.map(|i| vertices[i]);
+            if flat {
+                let color = vertices[provoking].color;
+                corners.iter_mut().for_each(|v| v.color = color);
+            }
+            let polygon = clip_polygon(corners.to_vec());
+            if polygon.len() < 3 {
+                continue;
+            }
+            let window: Vec<soft::Vertex> = polygon.iter().map(|v| self.to_window(v)).collect();
+            let Some(mode) = self.face_mode(soft::signed_area(&window[0], &window[1], &window[2])) else {
+                continue;
+            };
+            match mode {
+                GL_POINT => window.iter().for_each(|v| soft::point(&mut target, &pipeline, v)),
+                GL_LINE => {
+                    for (i, from) in window.iter().enumerate() {
+                        soft::line(&mut target, &pipeline, from, &window[(i + 1) % window.len()]);
+                    }
+                }
+                _ => {
+                    for i in 1..window.len() - 1 {
+                        soft::triangle(&mut target, &pipeline, &window[0], &window[i], &window[i + 1]);
+                    }
+                }
+            }
+        }
+        for ([a, b], provoking) in lines {
+            let (mut a, mut b) = (vertices[a], vertices[b]);
+            if flat {
+                (a.color, b.color) = (vertices[provoking].color, vertices[provoking].color);
+            }
+            if let Some((a, b)) = clip_line(a, b) {
+                soft::line(&mut target, &pipeline, &self.to_window(&a), &self.to_window(&b));
+            }
+        }
+        for point in points {
+            let point = &vertices[point];
+            if point.distances().iter().all(|&d| d >= 0.0) {
+                soft::point(&mut target, &pipeline, &self.to_window(point));
+            }
+        }
+        self.target = target;
+    }
+
+    // How a polygon of this signed area on the screen is drawn, or None if
+    // it is culled
+    fn face_mode(&self, area: f32) -> Option<GLenum> {
+        // Counterclockwise in GL's y-up window is clockwise on the screen
+        let front = (area > 0.0) == (self.front_face == GL_CCW);
+        if self.is_enabled(GL_CULL_FACE) {
+            let culled = match self.cull_face {
+                GL_FRONT => front,
+                GL_BACK => !front,
+                _ => true,
+            };
+            if culled {
+                return None;
+            }
+        }
+        Some(self.polygon_mode[if front { 0 } else { 1 }])
+    }
+
+    fn raster_state(&self) -> raster::State {
+        let mut flags = 0;
+        // Depth is only written where it is tested
+        if self.is_enabled(GL_DEPTH_TEST) {
+            flags |= raster::STATE_DEPTH_TEST;
+            if self.depth_mask {
+                flags |= raster::STATE_DEPTH_WRITE;
+            }
+        }
+        if self.is_enabled(GL_BLEND) {
+            flags |= raster::STATE_BLEND;
+        }
+        raster::State {
+            flags,
+            depth_func: self.depth_func - GL_NEVER + 1,
+            src_blend: blend_factor(self.blend_func.0),
+            dest_blend: blend_factor(self.blend_func.1),
+        }
+    }
+
+    // What glGet reports for `name`, if anything
+    fn values(&self, name: GLenum) -> Option<Vec<f32>> {
+        let int = |v: i32| Some(vec![v as f32]);
+        let flag = |b: bool| Some(vec![b as u8 as f32]);
+        match name {
+            GL_VIEWPORT => Some(self.viewport.map(|v| v as f32).to_vec()),
+            GL_SCISSOR_BOX => Some(self.scissor.map(|v| v as f32).to_vec()),
+            GL_DEPTH_RANGE => Some(vec![self.depth_range.0, self.depth_range.1]),
+            GL_COLOR_CLEAR_VALUE => Some(self.clear_color.to_vec()),
+            GL_DEPTH_CLEAR_VALUE => Some(vec![self.clear_depth]),
+            GL_CURRENT_COLOR => Some(self.color.to_vec()),
+            GL_MODELVIEW_MATRIX => self.modelview.last().map(|m| m.to_vec()),
+            GL_PROJECTION_MATRIX => self.projection.last().map(|m| m.to_vec()),
+            GL_TEXTURE_MATRIX => self.texture_matrix.last().map(|m| m.to_vec()),
+            GL_MODELVIEW_STACK_DEPTH => int(self.modelview.len() as i32),
+            GL_PROJECTION_STACK_DEPTH => int(self.projection.len() as i32),
+            GL_TEXTURE_STACK_DEPTH => int(self.texture_matrix.len() as i32),
+            GL_MAX_MODELVIEW_STACK_DEPTH => int(MAX_MODELVIEW_DEPTH as i32),
+            GL_MAX_PROJECTION_STACK_DEPTH => int(MAX_PROJECTION_DEPTH as i32),
+            GL_MAX_TEXTURE_STACK_DEPTH => int(MAX_TEXTURE_DEPTH as i32),
+            GL_MAX_TEXTURE_SIZE => int(MAX_TEXTURE_SIZE),
+            GL_MAX_VIEWPORT_DIMS => Some(vec![8192.0, 8192.0]),
+            GL_MATRIX_MODE => int(self.matrix_mode as i32),
+            GL_TEXTURE_BINDING_2D => int(self.bound_texture as i32),
+            GL_DEPTH_FUNC => int(self.depth_func as i32),
+            GL_DEPTH_WRITEMASK => flag(self.depth_mask),
+            GL_BLEND_SRC => int(self.blend_func.0 as i32),
+            GL_BLEND_DST => int(self.blend_func.1 as i32),
+            GL_ALPHA_TEST_FUNC => int(self.alpha_func.0 as i32),
+            GL_ALPHA_TEST_REF => Some(vec![self.alpha_func.1]),
+            GL_CULL_FACE_MODE => int(self.cull_face as i32),
+            GL_FRONT_FACE => int(self.front_face as i32),
+            GL_SHADE_MODEL => int(self.shade_model as i32),
+            GL_POLYGON_MODE => Some(self.polygon_mode.map(|mode| mode as f32).to_vec()),
+            GL_UNPACK_ALIGNMENT => int(self.unpack_alignment),
+            GL_PACK_ALIGNMENT => int(self.pack_alignment),
+            GL_RED_BITS | GL_GREEN_BITS | GL_BLUE_BITS | GL_ALPHA_BITS => int(8),
+            GL_DEPTH_BITS => int(24),
+            GL_STENCIL_BITS => int(0),
+            GL_DOUBLEBUFFER => flag(true),
+            GL_DEPTH_TEST | GL_CULL_FACE | GL_BLEND | GL_SCISSOR_TEST | GL_ALPHA_TEST | GL_TEXTURE_2D | GL_DITHER
+            | GL_LIGHTING => flag(self.is_enabled(name)),
+            GL_VERTEX_ARRAY => flag(self.vertex_array.enabled),
+            GL_COLOR_ARRAY => flag(self.color_array.enabled),
+            GL_TEXTURE_COORD_ARRAY => flag(self.tex_coord_array.enabled),
+            GL_NORMAL_ARRAY => flag(self.normal_array.enabled),
+            _ => None,
+        }
+    }
+
+    fn client_array(&mut self, array: GLenum) -> Option<&mut Pointer> {
+        match array {
+            GL_VERTEX_ARRAY => Some(&mut self.vertex_array),
+            GL_COLOR_ARRAY => Some(&mut self.color_array),
+            GL_TEXTURE_COORD_ARRAY => Some(&mut self.tex_coord_array),
+            GL_NORMAL_ARRAY => Some(&mut self.normal_array),
+            _ => None,
+        }
+    }
+
+    // Vertex `index` from the enabled client arrays
+    unsafe fn array_element(&self, index: usize) -> Input {
+        let color = if self.color_array.enabled {
+            self.color_array.read(index, [0.0, 0.0, 0.0, 1.0], true)
+        } else {
+            self.color
+        };
+        let tex_coord = if self.tex_coord_array.enabled {
+            self.tex_coord_array.read(index, [0.0, 0.0, 0.0, 1.0], false)
+        } else {
+            self.tex_coord
+        };
+        Input { position: self.vertex_array.read(index, [0.0, 0.0, 0.0, 1.0], false), color, tex_coord }
+    }
+
+    fn draw_elements(&mut self, mode: GLenum, indices: impl Iterator<Item = usize>) {
+        if !self.vertex_array.enabled {
+            return;
+        }
+        let inputs: Vec<Input> = indices.map(|index| unsafe { self.array_element(index) }).collect();
+        self.draw(mode, &inputs);
+    }
+
+    fn texture_mut(&mut self) -> &mut Texture {
+        self.textures.entry(self.bound_texture).or_default()
+    }
+}
+
+fn blend_factor(factor: GLenum) -> u32 {
+    match factor {
+        GL_ZERO => raster::BLEND_ZERO,
+        GL_SRC_COLOR => raster::BLEND_SRC_COLOR,
+        GL_ONE_MINUS_SRC_COLOR => raster::BLEND_INV_SRC_COLOR,
+        GL_SRC_ALPHA => raster::BLEND_SRC_ALPHA,
+        GL_ONE_MINUS_SRC_ALPHA => raster::BLEND_INV_SRC_ALPHA,
+        GL_DST_ALPHA => raster::BLEND_DEST_ALPHA,
+        GL_ONE_MINUS_DST_ALPHA => raster::BLEND_INV_DEST_ALPHA,
+        GL_DST_COLOR => raster::BLEND_DEST_COLOR,
+        GL_ONE_MINUS_DST_COLOR => raster::BLEND_INV_DEST_COLOR,
+        _ => raster::BLEND_ONE,
+    }
+}
+
+fn valid_blend_factor(factor: GLenum) -> bool {
+    factor == GL_ZERO || factor == GL_ONE || (GL_SRC_COLOR..=GL_ONE_MINUS_DST_COLOR).contains(&factor)
+}
+
+// The texture a draw samples, and how, if texturing is on and the bound
+// texture has an image
+fn bound_texture(
+    textures: &BTreeMap<GLuint, Texture>,
+    bound: GLuint,
+    enabled: bool,
+    env_mode: GLenum,
+    env_color: [f32; 4],
+) -> Option<(&Texture, Combine)> {
+    let texture = textures.get(&bound).filter(|texture| enabled && !texture.texels.is_empty())?;
+    let [r, g, b, a] = env_color;
+    let combine = match env_mode {
+        GL_REPLACE => Combine::Replace,
+        GL_DECAL => Combine::Decal,
+        GL_BLEND => Combine::Blend([a, r, g, b]),
+        GL_ADD => Combine::Add,
+        _ => Combine::Modulate,
+    };
+    Some((texture, combine))
+}
+
+// Bytes per pixel of a client pixel format, in unsigned bytes
+fn pixel_size(format: GLenum) -> Option<usize> {
+    match format {
+        GL_RGBA | GL_BGRA => Some(4),
+        GL_RGB | GL_BGR => Some(3),
+        GL_LUMINANCE_ALPHA => Some(2),
+        GL_LUMINANCE | GL_ALPHA => Some(1),
+        _ => None,
+    }
+}
+
+// A row's length in bytes, padded to the alignment
+fn row_bytes(width: usize, pixel: usize, alignment: i32) -> usize {
+    let alignment = alignment.max(1) as usize;
+    (width * pixel).div_ceil(alignment) * alignment
+}
+
+// One client pixel as ARGB
+fn texel_from(format: GLenum, bytes: &[u8]) -> u32 {
+    let [a, r, g, b] = match format {
+        GL_RGBA => [bytes[3], bytes[0], bytes[1], bytes[2]],
+        GL_BGRA => [bytes[3], bytes[2], bytes[1], bytes[0]],
+        GL_RGB => [0xFF, bytes[0], bytes[1], bytes[2]],
+        GL_BGR => [0xFF, bytes[2], bytes[1], bytes[0]],
+        GL_LUMINANCE_ALPHA => [bytes[1], bytes[0], bytes[0], bytes[0]],
+        GL_ALPHA => [bytes[0], 0xFF, 0xFF, 0xFF],
+        _ => [0xFF, bytes[0], bytes[0], bytes[0]],
+    };
+    u32::from_be_bytes([a, r, g, b])
+}
+
+// Copy `width` by `height` client pixels into `texture` at (x, y)
+unsafe fn unpack_pixels(
+    texture: &mut Texture,
+    (x, y): (usize, usize),
+    (width, height): (usize, usize),
+    format: GLenum,
+    alignment: i32,
+    pixels: *const c_void,
+) {
+    let Some(pixel) = pixel_size(format) else {
+        return;
+    };
+    if pixels.is_null() {
+        return;
+    }
+    let stride = row_bytes(width, pixel, alignment);
+    for row in 0..height {
+        let source = core::slice::from_raw_parts(pixels.cast::<u8>().add(row * stride), width * pixel);
+        let start = (y + row) * texture.width + x;
+        for (texel, bytes) in texture.texels[start..start + width].iter_mut().zip(source.chunks_exact(pixel)) {
+            *texel = texel_from(format, bytes);
+        }
+    }
+}
+
+static CONTEXTS: Mutex<BTreeMap<u64, Context>> = Mutex::new(BTreeMap::new());
+
+// The context current on each thread
+static CURRENT: Mutex<BTreeMap<u32, u64>> = Mutex::new(BTreeMap::new());
+
+// The pixel format set on each window, or on a device context with none
+static PIXEL_FORMATS: Mutex<BTreeMap<FormatKey, i32>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FormatKey {
+    Window(u64),
+    Dc(u64),
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn thread_id() -> u32 {
+    crate::win32::kernel32::GetCurrentThreadId()
+}
+
+// The window a device context draws on, and what its pixel format is kept
+// under
+fn drawable(hdc: Handle) -> Option<(Handle, FormatKey)> {
+    let gdi = GDI_MANAGER.lock();
+    let dc = gdi.dc(hdc)?;
+    Some(match dc.window {
+        Some(hwnd) if hwnd != Handle::NULL => (hwnd, FormatKey::Window(hwnd.0)),
+        _ => (Handle::NULL, FormatKey::Dc(hdc.0)),
+    })
+}
+
+// N values from an app's array, or None for a null pointer
+fn read_array<T: Copy, const N: usize>(values: *const T) -> Option<[T; N]> {
+    if values.is_null() {
+        return None;
+    }
+    Some(core::array::from_fn(|i| unsafe { values.add(i).read_unaligned() }))
+}
+
+// Run `f` on the current context, if there is one
+fn with_current<T>(f: impl FnOnce(&mut Context) -> T) -> Option<T> {
+    let id = *CURRENT.lock().get(&thread_id())?;
+    let mut contexts = CONTEXTS.lock();
+    contexts.get_mut(&id).map(f)
+}
+
+// Run a command on the current context, which fails between glBegin and
+// glEnd; an error it gives is kept for glGetError
+fn command(f: impl FnOnce(&mut Context) -> Result<(), GLenum>) {
+    with_current(|context| {
+        let result = if context.begun.is_some() { Err(GL_INVALID_OPERATION) } else { f(context) };
+        if let Err(error) = result {
+            context.fail(error);
+        }
+    });
+}
+
+// Store `values` through `out`, converted as glGet does
+fn get_values<T>(name: GLenum, out: *mut T, convert: impl Fn(f32) -> T) {
+    if out.is_null() {
+        return;
+    }
+    let values = with_current(|context| {
+        let values = if context.begun.is_some() { Err(GL_INVALID_OPERATION) } else { Ok(context.values(name)) };
+        match values {
+            Ok(Some(values)) => Some(values),
+            Ok(None) => {
+                context.fail(GL_INVALID_ENUM);
+                None
+            }
+            Err(error) => {
+                context.fail(error);
+                None
+            }
+        }
+    });
+    for (i, value) in values.flatten().unwrap_or_default().into_iter().enumerate() {
+        unsafe { out.add(i).write(convert(value)) };
+    }
+}
+
+// WGL and the pixel format calls
+
+/// ChoosePixelFormat - The pixel format closest to a descriptor
+#[no_mangle]
+pub extern "C" fn ChoosePixelFormat(hdc: Handle, descriptor: *const PixelFormatDescriptor) -> i32 {
+    if descriptor.is_null() || drawable(hdc).is_none() {
+        return 0;
+    }
+    PIXEL_FORMAT
+}
+
+/// SetPixelFormat - Set a window's pixel format, which can be done once
+#[no_mangle]
+pub extern "C" fn SetPixelFormat(hdc: Handle, format: i32, _descriptor: *const PixelFormatDescriptor) -> BOOL {
+    let Some((_, key)) = drawable(hdc) else {
+        return 0;
+    };
+    if !(1..=PIXEL_FORMAT_COUNT).contains(&format) {
+        return 0;
+    }
+    let mut formats = PIXEL_FORMATS.lock();
+    match formats.get(&key) {
+        Some(&set) => (set == format) as BOOL,
+        None => {
+            formats.insert(key, format);
+            1
+        }
+    }
+}
+
+/// GetPixelFormat - The pixel format set on a device context's window
+#[no_mangle]
+pub extern "C" fn GetPixelFormat(hdc: Handle) -> i32 {
+    drawable(hdc).and_then(|(_, key)| PIXEL_FORMATS.lock().get(&key).copied()).unwrap_or(0)
+}
+
+/// DescribePixelFormat - Fill in a pixel format's descriptor, and give how
+/// many formats there are
+#[no_mangle]
+pub extern "C" fn DescribePixelFormat(
+    hdc: Handle,
+    format: i32,
+    size: u32,
+    descriptor: *mut PixelFormatDescriptor,
+) -> i32 {
+    if drawable(hdc).is_none() || !(1..=PIXEL_FORMAT_COUNT).contains(&format) {
+        return 0;
+    }
+    if !descriptor.is_null() {
+        // Only as much as the app says it has room for
+        let length = (size as usize).min(core::mem::size_of::<PixelFormatDescriptor>());
+        let filled = pixel_format_descriptor();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (&filled as *const PixelFormatDescriptor).cast::<u8>(),
+                descriptor.cast(),
+                length,
+            );
+        }
+    }
+    PIXEL_FORMAT_COUNT
+}
+
+/// wglCreateContext - Make a rendering context for a device context whose
+/// pixel format is set
+#[no_mangle]
+pub extern "C" fn wglCreateContext(hdc: Handle) -> Handle {
+    let Some((hwnd, key)) = drawable(hdc) else {
+        return Handle::NULL;
+    };
+    if !PIXEL_FORMATS.lock().contains_key(&key) {
+        return Handle::NULL;
+    }
+    let (width, height) = surface::client_size(hwnd).unwrap_or(DEFAULT_SIZE);
+    let window = surface::open(hwnd, "OpenGL", width, height, true);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CONTEXTS.lock().insert(id, Context::new(hdc, hwnd, window, width, height));
+    Handle(id)
+}
+
+/// wglDeleteContext - Delete a context that is not current on another
+/// thread
+#[no_mangle]
+pub extern "C" fn wglDeleteContext(hglrc: Handle) -> BOOL {
+    let thread = thread_id();
+    let mut current = CURRENT.lock();
+    let mut contexts = CONTEXTS.lock();
+    let Some(context) = contexts.get(&hglrc.0) else {
+        return 0;
+    };
+    match context.thread {
+        Some(owner) if owner != thread => return 0,
+        Some(_) => {
+            current.remove(&thread);
+        }
+        None => {}
+    }
+    let window = contexts.remove(&hglrc.0).and_then(|context| context.window);
+    drop(contexts);
+    drop(current);
+    if let Some(id) = window {
+        surface::close(id);
+    }
+    1
+}
+
+/// wglMakeCurrent - Make a context current on this thread, drawing to a
+/// device context; a null context makes none current
+#[no_mangle]
+pub extern "C" fn wglMakeCurrent(hdc: Handle, hglrc: Handle) -> BOOL {
+    let thread = thread_id();
+    let mut current = CURRENT.lock();
+    let mut contexts = CONTEXTS.lock();
+    if hglrc == Handle::NULL {
+        if let Some(context) = current.remove(&thread).and_then(|id| contexts.get_mut(&id)) {
+            context.thread = None;
+        }
+        return 1;
+    }
+    let hwnd = match drawable(hdc) {
+        Some((hwnd, key)) if PIXEL_FORMATS.lock().contains_key(&key) => hwnd,
+        _ => return 0,
+    };
+    match contexts.get(&hglrc.0).map(|context| context.thread) {
+        None => return 0,
+        Some(Some(owner)) if owner != thread => return 0,
+        Some(_) => {}
+    }
+    if let Some(previous) = current.insert(thread, hglrc.0).and_then(|id| contexts.get_mut(&id)) {
+        previous.thread = None;
+    }
+    let Some(context) = contexts.get_mut(&hglrc.0) else {
+        return 0;
+    };
+    context.thread = Some(thread);
+    context.hdc = hdc;
+    context.hwnd = hwnd;
+    context.fit_window();
+    // The viewport and scissor start as the window the first time
+    if !context.sized {
+        context.sized = true;
+        let (width, height) = (context.target.width as i32, context.target.height as i32);
+        context.viewport = [0, 0, width, height];
+        context.scissor = [0, 0, width, height];
+    }
+    1
+}
+
+/// wglGetCurrentContext - The context current on this thread
+#[no_mangle]
+pub extern "C" fn wglGetCurrentContext() -> Handle {
+    CURRENT.lock().get(&thread_id()).map_or(Handle::NULL, |&id| Handle(id))
+}
+
+/// wglGetCurrentDC - The device context the current context draws to
+#[no_mangle]
+pub extern "C" fn wglGetCurrentDC() -> Handle {
+    with_current(|context| context.hdc).unwrap_or(Handle::NULL)
+}
+
+/// wglGetProcAddress - An extension function; there are none
+#[no_mangle]
+pub extern "C" fn wglGetProcAddress(_name: *const u8) -> *const c_void {
+    null()
+}
+
+/// wglShareLists - Share textures between contexts, which is not done
+#[no_mangle]
+pub extern "C" fn wglShareLists(_first: Handle, _second: Handle) -> BOOL {
+    0
+}
+
+/// wglSwapBuffers - Show what the context drawing to a device context drew
+#[no_mangle]
+pub extern "C" fn wglSwapBuffers(hdc: Handle) -> BOOL {
+    let Some((hwnd, _)) = drawable(hdc) else {
+        return 0;
+    };
+    let thread = thread_id();
+    let current = CURRENT.lock().get(&thread).copied();
+    let mut contexts = CONTEXTS.lock();
+    // The current context if it draws there, otherwise any that does
+    let id = current
+        .filter(|id| contexts.get(id).is_some_and(|context| context.hwnd == hwnd && context.hdc == hdc))
+        .or_else(|| contexts.iter().find(|(_, context)| context.hdc == hdc).map(|(&id, _)| id))
+        .or_else(|| contexts.iter().find(|(_, context)| context.hwnd == hwnd).map(|(&id, _)| id));
+    let Some(context) = id.and_then(|id| contexts.get_mut(&id)) else {
+        return 0;
+    };
+    if let Some(window) = context.window {
+        surface::show(window, &context.target, true);
+    }
+    context.fit_window();
+    1
+}
+
+/// SwapBuffers - Show the back buffer of the window a device context draws
+/// on
+#[no_mangle]
+pub extern "C" fn SwapBuffers(hdc: Handle) -> BOOL {
+    wglSwapBuffers(hdc)
+}
+
+// State
+
+/// glGetError - Take the first error since the last call
+#[no_mangle]
+pub extern "C" fn glGetError() -> GLenum {
+    with_current(|context| core::mem::replace(&mut context.error, GL_NO_ERROR)).unwrap_or(GL_NO_ERROR)
+}
+
+/// glGetString - Describe the implementation
+#[no_mangle]
+pub extern "C" fn glGetString(name: GLenum) -> *const GLubyte {
+    let string = with_current(|context| {
+        let string = match name {
+            GL_VENDOR => Some(VENDOR),
+            GL_RENDERER => Some(RENDERER),
+            GL_VERSION => Some(VERSION),
+            GL_EXTENSIONS => Some(EXTENSIONS),
+            _ => None,
+        };
+        if string.is_none() {
+            context.fail(GL_INVALID_ENUM);
+        }
+        string
+    });
+    string.flatten().map_or(null(), |string| string.as_ptr())
+}
+
+/// glGetIntegerv - Read state as integers
+#[no_mangle]
+pub extern "C" fn glGetIntegerv(name: GLenum, out: *mut GLint) {
+    get_values(name, out, |value| if value < 0.0 { (value - 0.5) as GLint } else { (value + 0.5) as GLint });
+}
+
+/// glGetFloatv - Read state as floats
+#[no_mangle]
+pub extern "C" fn glGetFloatv(name: GLenum, out: *mut GLfloat) {
+    get_values(name, out, |value| value);
+}
+
+/// glGetDoublev - Read state as doubles
+#[no_mangle]
+pub extern "C" fn glGetDoublev(name: GLenum, out: *mut GLdouble) {
+    get_values(name, out, |value| value as GLdouble);
+}
+
+/// glGetBooleanv - Read state as booleans
+#[no_mangle]
+pub extern "C" fn glGetBooleanv(name: GLenum, out: *mut GLboolean) {
+    get_values(name, out, |value| if value != 0.0 { GL_TRUE } else { GL_FALSE });
+}
+
+/// glIsEnabled - Whether a capability is on
+#[no_mangle]
+pub extern "C" fn glIsEnabled(cap: GLenum) -> GLboolean {
+    with_current(|context| context.is_enabled(cap) as GLboolean).unwrap_or(GL_FALSE)
+}
+
+// Capabilities are kept whatever they are; only those in the module
+// comment change what is drawn
+fn set_enabled(cap: GLenum, on: bool) {
+    command(|context| {
+        if on {
+            context.enabled.insert(cap);
+        } else {
+            context.enabled.remove(&cap);
+        }
+        Ok(())
+    });
+}
+
+/// glEnable - Turn a capability on
+#[no_mangle]
+pub extern "C" fn glEnable(cap: GLenum) {
+    set_enabled(cap, true);
+}
+
+/// glDisable - Turn a capability off
+#[no_mangle]
+pub extern "C" fn glDisable(cap: GLenum) {
+    set_enabled(cap, false);
+}
+
+/// glHint - Take a hint, which changes nothing
+#[no_mangle]
+pub extern "C" fn glHint(_target: GLenum, _mode: GLenum) {
+    command(|_| Ok(()));
+}
+
+/// glFlush - Nothing is queued, so there is nothing to flush
+#[no_mangle]
+pub extern "C" fn glFlush() {
+    command(|_| Ok(()));
+}
+
+/// glFinish - Drawing is done by the time each call returns
+#[no_mangle]
+pub extern "C" fn glFinish() {
+    command(|_| Ok(()));
+}
+
+/// glViewport - Map normalized device coordinates to the window
+#[no_mangle]
+pub extern "C" fn glViewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    command(|context| {
+        if width < 0 || height < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        context.viewport = [x, y, width.min(8192), height.min(8192)];
+        Ok(())
+    });
+}
+
+/// glDepthRange - Map normalized depth to the depth buffer
+#[no_mangle]
+pub extern "C" fn glDepthRange(near: GLclampd, far: GLclampd) {
+    command(|context| {
+        context.depth_range = (near.clamp(0.0, 1.0) as f32, far.clamp(0.0, 1.0) as f32);
+        Ok(())
+    });
+}
+
+/// glScissor - Set the scissor box, from the window's bottom left
+#[no_mangle]
+pub extern "C" fn glScissor(x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    command(|context| {
+        if width < 0 || height < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        context.scissor = [x, y, width, height];
+        Ok(())
+    });
+}
+
+/// glClear - Clear the color and depth buffers inside the scissor
+#[no_mangle]
+pub extern "C" fn glClear(mask: GLbitfield) {
+    command(|context| context.clear(mask));
+}
+
+/// glClearColor - The color glClear clears to
+#[no_mangle]
+pub extern "C" fn glClearColor(red: GLclampf, green: GLclampf, blue: GLclampf, alpha: GLclampf) {
+    command(|context| {
+        context.clear_color = [red, green, blue, alpha].map(|c| c.clamp(0.0, 1.0));
+        Ok(())
+    });
+}
+
+/// glClearDepth - The depth glClear clears to
+#[no_mangle]
+pub extern "C" fn glClearDepth(depth: GLclampd) {
+    command(|context| {
+        context.clear_depth = depth.clamp(0.0, 1.0) as f32;
+        Ok(())
+    });
+}
+
+/// glDepthFunc - How the depth test compares
+#[no_mangle]
+pub extern "C" fn glDepthFunc(func: GLenum) {
+    command(|context| {
+        if !(GL_NEVER..=GL_ALWAYS).contains(&func) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.depth_func = func;
+        Ok(())
+    });
+}
+
+/// glDepthMask - Whether passing fragments write depth
+#[no_mangle]
+pub extern "C" fn glDepthMask(flag: GLboolean) {
+    command(|context| {
+        context.depth_mask = flag != GL_FALSE;
+        Ok(())
+    });
+}
+
+/// glBlendFunc - The source and destination blend factors
+#[no_mangle]
+pub extern "C" fn glBlendFunc(source: GLenum, dest: GLenum) {
+    command(|context| {
+        if !valid_blend_factor(source) || !valid_blend_factor(dest) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.blend_func = (source, dest);
+        Ok(())
+    });
+}
+
+/// glAlphaFunc - How the alpha test compares
+#[no_mangle]
+pub extern "C" fn glAlphaFunc(func: GLenum, reference: GLclampf) {
+    command(|context| {
+        if !(GL_NEVER..=GL_ALWAYS).contains(&func) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.alpha_func = (func, reference.clamp(0.0, 1.0));
+        Ok(())
+    });
+}
+
+/// glCullFace - Which faces culling drops
+#[no_mangle]
+pub extern "C" fn glCullFace(mode: GLenum) {
+    command(|context| {
+        if ![GL_FRONT, GL_BACK, GL_FRONT_AND_BACK].contains(&mode) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.cull_face = mode;
+        Ok(())
+    });
+}
+
+/// glFrontFace - Which winding faces the viewer
+#[no_mangle]
+pub extern "C" fn glFrontFace(mode: GLenum) {
+    command(|context| {
+        if mode != GL_CW && mode != GL_CCW {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.front_face = mode;
+        Ok(())
+    });
+}
+
+/// glShadeModel - Flat or smooth shading
+#[no_mangle]
+pub extern "C" fn glShadeModel(mode: GLenum) {
+    command(|context| {
+        if mode != GL_FLAT && mode != GL_SMOOTH {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.shade_model = mode;
+        Ok(())
+    });
+}
+
+/// glPolygonMode - Draw faces as points, outlines or filled
+#[no_mangle]
+pub extern "C" fn glPolygonMode(face: GLenum, mode: GLenum) {
+    command(|context| {
+        if ![GL_POINT, GL_LINE, GL_FILL].contains(&mode) {
+            return Err(GL_INVALID_ENUM);
+        }
+        match face {
+            GL_FRONT => context.polygon_mode[0] = mode,
+            GL_BACK => context.polygon_mode[1] = mode,
+            GL_FRONT_AND_BACK => context.polygon_mode = [mode; 2],
+            _ => return Err(GL_INVALID_ENUM),
+        }
+        Ok(())
+    });
+}
+
+/// glLineWidth - Lines are always one pixel wide
+#[no_mangle]
+pub extern "C" fn glLineWidth(width: GLfloat) {
+    command(|_| if width > 0.0 { Ok(()) } else { Err(GL_INVALID_VALUE) });
+}
+
+/// glPointSize - Points are always one pixel
+#[no_mangle]
+pub extern "C" fn glPointSize(size: GLfloat) {
+    command(|_| if size > 0.0 { Ok(()) } else { Err(GL_INVALID_VALUE) });
+}
+
+/// glPixelStorei - Row alignment of pixels passed in and out
+#[no_mangle]
+pub extern "C" fn glPixelStorei(name: GLenum, param: GLint) {
+    command(|context| {
+        if ![1, 2, 4, 8].contains(&param) {
+            return Err(GL_INVALID_VALUE);
+        }
+        match name {
+            GL_UNPACK_ALIGNMENT => context.unpack_alignment = param,
+            GL_PACK_ALIGNMENT => context.pack_alignment = param,
+            _ => return Err(GL_INVALID_ENUM),
+        }
+        Ok(())
+    });
+}
+
+/// glReadPixels - Read back colors or depth, rows from the bottom
+#[no_mangle]
+pub extern "C" fn glReadPixels(
+    x: GLint,
+    y: GLint,
+    width: GLsizei,
+    height: GLsizei,
+    format: GLenum,
+    kind: GLenum,
+    pixels: *mut c_void,
+) {
+    command(|context| {
+        if width < 0 || height < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        let depth = format == GL_DEPTH_COMPONENT;
+        let pixel = match (format, kind) {
+            (GL_DEPTH_COMPONENT, GL_FLOAT) => 4,
+            (GL_DEPTH_COMPONENT, _) => return Err(GL_INVALID_ENUM),
+            (_, GL_UNSIGNED_BYTE) => pixel_size(format).ok_or(GL_INVALID_ENUM)?,
+            _ => return Err(GL_INVALID_ENUM),
+        };
+        if pixels.is_null() {
+            return Ok(());
+        }
+        let target = &context.target;
+        let stride = row_bytes(width as usize, pixel, context.pack_alignment);
+        for row in 0..height as usize {
+            let start = unsafe { pixels.cast::<u8>().add(row * stride) };
+            let out = unsafe { core::slice::from_raw_parts_mut(start, width as usize * pixel) };
+            let window_y = y + row as i32;
+            for (column, bytes) in out.chunks_exact_mut(pixel).enumerate() {
+                let window_x = x + column as i32;
+                // Pixels outside the window read as nothing in particular
+                if window_x < 0
+                    || window_y < 0
+                    || window_x as usize >= target.width
+                    || window_y as usize >= target.height
+                {
+                    bytes.fill(0);
+                    continue;
+                }
+                let index = (target.height - 1 - window_y as usize) * target.width + window_x as usize;
+                if depth {
+                    bytes.copy_from_slice(&target.depth[index].to_le_bytes());
+                    continue;
+                }
+                let [a, r, g, b] = target.color[index].to_be_bytes();
+                match format {
+                    GL_RGBA => bytes.copy_from_slice(&[r, g, b, a]),
+                    GL_BGRA => bytes.copy_from_slice(&[b, g, r, a]),
+                    GL_RGB => bytes.copy_from_slice(&[r, g, b]),
+                    GL_BGR => bytes.copy_from_slice(&[b, g, r]),
+                    GL_LUMINANCE_ALPHA => bytes.copy_from_slice(&[r, a]),
+                    GL_ALPHA => bytes[0] = a,
+                    _ => bytes[0] = r,
+                }
+            }
+        }
+        Ok(())
+    });
+}
+
+// Matrices
+
+/// glMatrixMode - Which stack the matrix calls change
+#[no_mangle]
+pub extern "C" fn glMatrixMode(mode: GLenum) {
+    command(|context| {
+        if ![GL_MODELVIEW, GL_PROJECTION, GL_TEXTURE].contains(&mode) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.matrix_mode = mode;
+        Ok(())
+    });
+}
+
+/// glLoadIdentity - Reset the current matrix
+#[no_mangle]
+pub extern "C" fn glLoadIdentity() {
+    command(|context| {
+        *context.current_matrix() = IDENTITY;
+        Ok(())
+    });
+}
+
+/// glLoadMatrixf - Replace the current matrix, given column by column
+#[no_mangle]
+pub extern "C" fn glLoadMatrixf(m: *const GLfloat) {
+    if let Some(matrix) = read_array::<_, 16>(m) {
+        command(|context| {
+            *context.current_matrix() = matrix;
+            Ok(())
+        });
+    }
+}
+
+/// glLoadMatrixd - glLoadMatrixf in doubles
+#[no_mangle]
+pub extern "C" fn glLoadMatrixd(m: *const GLdouble) {
+    if let Some(matrix) = read_array::<_, 16>(m) {
+        glLoadMatrixf(matrix.map(|v| v as f32).as_ptr());
+    }
+}
+
+/// glMultMatrixf - Multiply the current matrix by another on the right
+#[no_mangle]
+pub extern "C" fn glMultMatrixf(m: *const GLfloat) {
+    if let Some(matrix) = read_array::<_, 16>(m) {
+        multiply_by(matrix);
+    }
+}
+
+/// glMultMatrixd - glMultMatrixf in doubles
+#[no_mangle]
+pub extern "C" fn glMultMatrixd(m: *const GLdouble) {
+    if let Some(matrix) = read_array::<_, 16>(m) {
+        multiply_by(matrix.map(|v| v as f32));
+    }
+}
+
+/// glPushMatrix - Push a copy of the current matrix
+#[no_mangle]
+pub extern "C" fn glPushMatrix() {
+    command(|context| {
+        let (stack, depth) = context.stack();
+        if stack.len() >= depth {
+            return Err(GL_STACK_OVERFLOW);
+        }
+        let top = *stack.last().unwrap_or(&IDENTITY);
+        stack.push(top);
+        Ok(())
+    });
+}
+
+/// glPopMatrix - Go back to the matrix pushed last
+#[no_mangle]
+pub extern "C" fn glPopMatrix() {
+    command(|context| {
+        let (stack, _) = context.stack();
+        if stack.len() <= 1 {
+            return Err(GL_STACK_UNDERFLOW);
+        }
+        stack.pop();
+        Ok(())
+    });
+}
+
+fn multiply_by(matrix: Matrix) {
+    command(|context| {
+        context.multiply_current(&matrix);
+        Ok(())
+    });
+}
+
+/// glTranslatef - Move what follows
+#[no_mangle]
+pub extern "C" fn glTranslatef(x: GLfloat, y: GLfloat, z: GLfloat) {
+    multiply_by(translation(x, y, z));
+}
+
+/// glTranslated - glTranslatef in doubles
+#[no_mangle]
+pub extern "C" fn glTranslated(x: GLdouble, y: GLdouble, z: GLdouble) {
+    multiply_by(translation(x as f32, y as f32, z as f32));
+}
+
+/// glRotatef - Turn what follows by degrees about an axis
+#[no_mangle]
+pub extern "C" fn glRotatef(angle: GLfloat, x: GLfloat, y: GLfloat, z: GLfloat) {
+    multiply_by(rotation(angle, x, y, z));
+}
+
+/// glRotated - glRotatef in doubles
+#[no_mangle]
+pub extern "C" fn glRotated(angle: GLdouble, x: GLdouble, y: GLdouble, z: GLdouble) {
+    multiply_by(rotation(angle as f32, x as f32, y as f32, z as f32));
+}
+
+/// glScalef - Scale what follows
+#[no_mangle]
+pub extern "C" fn glScalef(x: GLfloat, y: GLfloat, z: GLfloat) {
+    multiply_by(scaling(x, y, z));
+}
+
+/// glScaled - glScalef in doubles
+#[no_mangle]
+pub extern "C" fn glScaled(x: GLdouble, y: GLdouble, z: GLdouble) {
+    multiply_by(scaling(x as f32, y as f32, z as f32));
+}
+
+/// glOrtho - Multiply by a parallel projection
+#[no_mangle]
+pub extern "C" fn glOrtho(
+    left: GLdouble,
+    right: GLdouble,
+    bottom: GLdouble,
+    top: GLdouble,
+    near: GLdouble,
+    far: GLdouble,
+) {
+    command(|context| {
+        if left == right || bottom == top || near == far {
+            return Err(GL_INVALID_VALUE);
+        }
+        let (width, height, depth) = (right - left, top - bottom, far - near);
+        let mut m = IDENTITY;
+        (m[0], m[5], m[10]) = ((2.0 / width) as f32, (2.0 / height) as f32, (-2.0 / depth) as f32);
+        m[12] = (-(right + left) / width) as f32;
+        m[13] = (-(top + bottom) / height) as f32;
+        m[14] = (-(far + near) / depth) as f32;
+        context.multiply_current(&m);
+        Ok(())
+    });
+}
+
+/// glFrustum - Multiply by a perspective projection
+#[no_mangle]
+pub extern "C" fn glFrustum(
+    left: GLdouble,
+    right: GLdouble,
+    bottom: GLdouble,
+    top: GLdouble,
+    near: GLdouble,
+    far: GLdouble,
+) {
+    command(|context| {
+        if near <= 0.0 || far <= 0.0 || left == right || bottom == top || near == far {
+            return Err(GL_INVALID_VALUE);
+        }
+        let (width, height, depth) = (right - left, top - bottom, far - near);
+        let mut m = [0.0; 16];
+        m[0] = (2.0 * near / width) as f32;
+        m[5] = (2.0 * near / height) as f32;
+        m[8] = ((right + left) / width) as f32;
+        m[9] = ((top + bottom) / height) as f32;
+        m[10] = (-(far + near) / depth) as f32;
+        m[11] = -1.0;
+        m[14] = (-2.0 * far * near / depth) as f32;
+        context.multiply_current(&m);
+        Ok(())
+    });
+}
+
+// Immediate mode
+
+/// glBegin - Start a primitive's vertices
+#[no_mangle]
+pub extern "C" fn glBegin(mode: GLenum) {
+    command(|context| {
+        if mode > GL_POLYGON {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.begun = Some(mode);
+        context.inputs.clear();
+        Ok(())
+    });
+}
+
+/// glEnd - Draw the vertices given since glBegin
+#[no_mangle]
+pub extern "C" fn glEnd() {
+    with_current(|context| {
+        let Some(mode) = context.begun.take() else {
+            context.fail(GL_INVALID_OPERATION);
+            return;
+        };
+        let inputs = core::mem::take(&mut context.inputs);
+        context.draw(mode, &inputs);
+        context.inputs = inputs;
+    });
+}
+
+fn vertex(position: [f32; 4]) {
+    with_current(|context| {
+        // Outside glBegin and glEnd a vertex does nothing
+        if context.begun.is_some() {
+            let input = Input { position, color: context.color, tex_coord: context.tex_coord };
+            context.inputs.push(input);
+        }
+    });
+}
+
+/// glVertex2f - A vertex at z = 0
+#[no_mangle]
+pub extern "C" fn glVertex2f(x: GLfloat, y: GLfloat) {
+    vertex([x, y, 0.0, 1.0]);
+}
+
+/// glVertex2i - glVertex2f in integers
+#[no_mangle]
+pub extern "C" fn glVertex2i(x: GLint, y: GLint) {
+    vertex([x as f32, y as f32, 0.0, 1.0]);
+}
+
+/// glVertex3f - A vertex
+#[no_mangle]
+pub extern "C" fn glVertex3f(x: GLfloat, y: GLfloat, z: GLfloat) {
+    vertex([x, y, z, 1.0]);
+}
+
+/// glVertex4f - A vertex with its w
+#[no_mangle]
+pub extern "C" fn glVertex4f(x: GLfloat, y: GLfloat, z: GLfloat, w: GLfloat) {
+    vertex([x, y, z, w]);
+}
+
+/// glVertex2fv - glVertex2f from an array
+#[no_mangle]
+pub extern "C" fn glVertex2fv(v: *const GLfloat) {
+    if let Some([x, y]) = read_array(v) {
+        vertex([x, y, 0.0, 1.0]);
+    }
+}
+
+/// glVertex3fv - glVertex3f from an array
+#[no_mangle]
+pub extern "C" fn glVertex3fv(v: *const GLfloat) {
+    if let Some([x, y, z]) = read_array(v) {
+        vertex([x, y, z, 1.0]);
+    }
+}
+
+fn set_color(color: [f32; 4]) {
+    with_current(|context| context.color = color);
+}
+
+/// glColor3f - The current color, opaque
+#[no_mangle]
+pub extern "C" fn glColor3f(red: GLfloat, green: GLfloat, blue: GLfloat) {
+    set_color([red, green, blue, 1.0]);
+}
+
+/// glColor4f - The current color
+#[no_mangle]
+pub extern "C" fn glColor4f(red: GLfloat, green: GLfloat, blue: GLfloat, alpha: GLfloat) {
+    set_color([red, green, blue, alpha]);
+}
+
+/// glColor3ub - glColor3f in bytes
+#[no_mangle]
+pub extern "C" fn glColor3ub(red: GLubyte, green: GLubyte, blue: GLubyte) {
+    set_color([red, green, blue, 255].map(|c| c as f32 / 255.0));
+}
+
+/// glColor4ub - glColor4f in bytes
+#[no_mangle]
+pub extern "C" fn glColor4ub(red: GLubyte, green: GLubyte, blue: GLubyte, alpha: GLubyte) {
+    set_color([red, green, blue, alpha].map(|c| c as f32 / 255.0));
+}
+
+/// glColor3fv - glColor3f from an array
+#[no_mangle]
+pub extern "C" fn glColor3fv(v: *const GLfloat) {
+    if let Some([red, green, blue]) = read_array(v) {
+        set_color([red, green, blue, 1.0]);
+    }
+}
+
+/// glColor4fv - glColor4f from an array
+#[no_mangle]
+pub extern "C" fn glColor4fv(v: *const GLfloat) {
+    if let Some(color) = read_array(v) {
+        set_color(color);
+    }
+}
+
+/// glTexCoord2f - The current texture coordinates
+#[no_mangle]
+pub extern "C" fn glTexCoord2f(s: GLfloat, t: GLfloat) {
+    with_current(|context| context.tex_coord = [s, t, 0.0, 1.0]);
+}
+
+/// glTexCoord2fv - glTexCoord2f from an array
+#[no_mangle]
+pub extern "C" fn glTexCoord2fv(v: *const GLfloat) {
+    if let Some([s, t]) = read_array(v) {
+        glTexCoord2f(s, t);
+    }
+}
+
+/// glNormal3f - The current normal, kept for glGet as there is no lighting
+#[no_mangle]
+pub extern "C" fn glNormal3f(x: GLfloat, y: GLfloat, z: GLfloat) {
+    with_current(|context| context.normal = [x, y, z]);
+}
+
+/// glNormal3fv - glNormal3f from an array
+#[no_mangle]
+pub extern "C" fn glNormal3fv(v: *const GLfloat) {
+    if let Some([x, y, z]) = read_array(v) {
+        glNormal3f(x, y, z);
+    }
+}
+
+// Vertex arrays
+
+fn set_client_state(array: GLenum, on: bool) {
+    command(|context| {
+        context.client_array(array).ok_or(GL_INVALID_ENUM)?.enabled = on;
+        Ok(())
+    });
+}
+
+/// glEnableClientState - Draw from a vertex array
+#[no_mangle]
+pub extern "C" fn glEnableClientState(array: GLenum) {
+    set_client_state(array, true);
+}
+
+/// glDisableClientState - Stop drawing from a vertex array
+#[no_mangle]
+pub extern "C" fn glDisableClientState(array: GLenum) {
+    set_client_state(array, false);
+}
+
+fn set_pointer(array: GLenum, size: GLint, kind: GLenum, stride: GLsizei, pointer: *const c_void) {
+    command(|context| {
+        if stride < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        let (sizes, kinds): (&[i32], &[GLenum]) = match array {
+            GL_VERTEX_ARRAY => (&[2, 3, 4], &[GL_SHORT, GL_INT, GL_FLOAT, GL_DOUBLE]),
+            GL_COLOR_ARRAY => (
+                &[3, 4],
+                &[GL_BYTE, GL_UNSIGNED_BYTE, GL_SHORT, GL_UNSIGNED_SHORT, GL_INT, GL_UNSIGNED_INT, GL_FLOAT, GL_DOUBLE],
+            ),
+            GL_TEXTURE_COORD_ARRAY => (&[1, 2, 3, 4], &[GL_SHORT, GL_INT, GL_FLOAT, GL_DOUBLE]),
+            _ => (&[3], &[GL_BYTE, GL_SHORT, GL_INT, GL_FLOAT, GL_DOUBLE]),
+        };
+        if !sizes.contains(&size) {
+            return Err(GL_INVALID_VALUE);
+        }
+        if !kinds.contains(&kind) {
+            return Err(GL_INVALID_ENUM);
+        }
+        let target = context.client_array(array).ok_or(GL_INVALID_ENUM)?;
+        (target.size, target.kind, target.stride, target.address) = (size, kind, stride, pointer as usize);
+        Ok(())
+    });
+}
+
+/// glVertexPointer - Where vertex positions are
+#[no_mangle]
+pub extern "C" fn glVertexPointer(size: GLint, kind: GLenum, stride: GLsizei, pointer: *const c_void) {
+    set_pointer(GL_VERTEX_ARRAY, size, kind, stride, pointer);
+}
+
+/// glColorPointer - Where vertex colors are
+#[no_mangle]
+pub extern "C" fn glColorPointer(size: GLint, kind: GLenum, stride: GLsizei, pointer: *const c_void) {
+    set_pointer(GL_COLOR_ARRAY, size, kind, stride, pointer);
+}
+
+/// glTexCoordPointer - Where texture coordinates are
+#[no_mangle]
+pub extern "C" fn glTexCoordPointer(size: GLint, kind: GLenum, stride: GLsizei, pointer: *const c_void) {
+    set_pointer(GL_TEXTURE_COORD_ARRAY, size, kind, stride, pointer);
+}
+
+/// glNormalPointer - Where normals are
+#[no_mangle]
+pub extern "C" fn glNormalPointer(kind: GLenum, stride: GLsizei, pointer: *const c_void) {
+    set_pointer(GL_NORMAL_ARRAY, 3, kind, stride, pointer);
+}
+
+fn valid_mode(mode: GLenum) -> Result<(), GLenum> {
+    if mode > GL_POLYGON {
+        Err(GL_INVALID_ENUM)
+    } else {
+        Ok(())
+    }
+}
+
+/// glDrawArrays - Draw a run of vertices from the arrays
+#[no_mangle]
+pub extern "C" fn glDrawArrays(mode: GLenum, first: GLint, count: GLsizei) {
+    command(|context| {
+        valid_mode(mode)?;
+        if first < 0 || count < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        context.draw_elements(mode, first as usize..first as usize + count as usize);
+        Ok(())
+    });
+}
+
+/// glDrawElements - Draw the array vertices an index list picks
+#[no_mangle]
+pub extern "C" fn glDrawElements(mode: GLenum, count: GLsizei, kind: GLenum, indices: *const c_void) {
+    command(|context| {
+        valid_mode(mode)?;
+        if count < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        if indices.is_null() {
+            return Ok(());
+        }
+        let count = count as usize;
+        match kind {
+            GL_UNSIGNED_BYTE => {
+                let indices = unsafe { core::slice::from_raw_parts(indices.cast::<u8>(), count) };
+                context.draw_elements(mode, indices.iter().map(|&i| i as usize));
+            }
+            GL_UNSIGNED_SHORT => {
+                let indices = indices.cast::<u16>();
+                context.draw_elements(mode, (0..count).map(|i| unsafe { indices.add(i).read_unaligned() } as usize));
+            }
+            GL_UNSIGNED_INT => {
+                let indices = indices.cast::<u32>();
+                context.draw_elements(mode, (0..count).map(|i| unsafe { indices.add(i).read_unaligned() } as usize));
+            }
+            _ => return Err(GL_INVALID_ENUM),
+        }
+        Ok(())
+    });
+}
+
+// Textures
+
+/// glGenTextures - Names for new textures
+#[no_mangle]
+pub extern "C" fn glGenTextures(count: GLsizei, names: *mut GLuint) {
+    command(|context| {
+        if count < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        if names.is_null() {
+            return Ok(());
+        }
+        for i in 0..count as usize {
+            while context.textures.contains_key(&context.next_texture) || context.next_texture == 0 {
+                context.next_texture = context.next_texture.wrapping_add(1);
+            }
+            unsafe { names.add(i).write(context.next_texture) };
+            context.next_texture = context.next_texture.wrapping_add(1);
+        }
+        Ok(())
+    });
+}
+
+/// glDeleteTextures - Delete textures; one bound goes back to the default
+#[no_mangle]
+pub extern "C" fn glDeleteTextures(count: GLsizei, names: *const GLuint) {
+    command(|context| {
+        if count < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        if names.is_null() {
+            return Ok(());
+        }
+        for i in 0..count as usize {
+            let name = unsafe { names.add(i).read_unaligned() };
+            if name == 0 {
+                continue;
+            }
+            context.textures.remove(&name);
+            if context.bound_texture == name {
+                context.bound_texture = 0;
+            }
+        }
+        Ok(())
+    });
+}
+
+/// glBindTexture - Make a texture the 2D one, creating it the first time
+#[no_mangle]
+pub extern "C" fn glBindTexture(target: GLenum, name: GLuint) {
+    command(|context| {
+        if target != GL_TEXTURE_2D {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.textures.entry(name).or_default();
+        context.bound_texture = name;
+        Ok(())
+    });
+}
+
+/// glIsTexture - Whether a name has been bound as a texture
+#[no_mangle]
+pub extern "C" fn glIsTexture(name: GLuint) -> GLboolean {
+    with_current(|context| (name != 0 && context.textures.contains_key(&name)) as GLboolean).unwrap_or(GL_FALSE)
+}
+
+/// glTexImage2D - Give the bound texture an image; levels past the base
+/// are accepted and not kept
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn glTexImage2D(
+    target: GLenum,
+    level: GLint,
+    internal_format: GLint,
+    width: GLsizei,
+    height: GLsizei,
+    border: GLint,
+    format: GLenum,
+    kind: GLenum,
+    pixels: *const c_void,
+) {
+    command(|context| {
+        if target != GL_TEXTURE_2D || pixel_size(format).is_none() || kind != GL_UNSIGNED_BYTE {
+            return Err(GL_INVALID_ENUM);
+        }
+        let (has_color, has_alpha) = match internal_format as GLenum {
+            1 | GL_LUMINANCE => (true, false),
+            2 | GL_LUMINANCE_ALPHA => (true, true),
+            3 | GL_RGB | GL_RGB8 => (true, false),
+            4 | GL_RGBA | GL_RGBA8 => (true, true),
+            GL_ALPHA => (false, true),
+            _ => return Err(GL_INVALID_VALUE),
+        };
+        if level < 0
+            || border != 0
+            || !(0..=MAX_TEXTURE_SIZE).contains(&width)
+            || !(0..=MAX_TEXTURE_SIZE).contains(&height)
+        {
+            return Err(GL_INVALID_VALUE);
+        }
+        if level > 0 {
+            return Ok(());
+        }
+        let alignment = context.unpack_alignment;
+        let (width, height) = (width as usize, height as usize);
+        let texture = context.texture_mut();
+        (texture.width, texture.height, texture.has_color, texture.has_alpha) = (width, height, has_color, has_alpha);
+        texture.texels = vec![0xFF00_0000; width * height];
+        unsafe { unpack_pixels(texture, (0, 0), (width, height), format, alignment, pixels) };
+        Ok(())
+    });
+}
+
+/// glTexSubImage2D - Replace part of the bound texture's image
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn glTexSubImage2D(
+    target: GLenum,
+    level: GLint,
+    x: GLint,
+    y: GLint,
+    width: GLsizei,
+    height: GLsizei,
+    format: GLenum,
+    kind: GLenum,
+    pixels: *const c_void,
+) {
+    command(|context| {
+        if target != GL_TEXTURE_2D || pixel_size(format).is_none() || kind != GL_UNSIGNED_BYTE {
+            return Err(GL_INVALID_ENUM);
+        }
+        if level < 0 || x < 0 || y < 0 || width < 0 || height < 0 {
+            return Err(GL_INVALID_VALUE);
+        }
+        if level > 0 {
+            return Ok(());
+        }
+        let alignment = context.unpack_alignment;
+        let texture = context.texture_mut();
+        let (x, y, width, height) = (x as usize, y as usize, width as usize, height as usize);
+        if x + width > texture.width || y + height > texture.height {
+            return Err(GL_INVALID_VALUE);
+        }
+        unsafe { unpack_pixels(texture, (x, y), (width, height), format, alignment, pixels) };
+        Ok(())
+    });
+}
+
+/// glTexParameteri - Set the bound texture's filters and wrapping
+#[no_mangle]
+pub extern "C" fn glTexParameteri(target: GLenum, name: GLenum, param: GLint) {
+    command(|context| {
+        if target != GL_TEXTURE_2D {
+            return Err(GL_INVALID_ENUM);
+        }
+        let param = param as GLenum;
+        let texture = context.texture_mut();
+        let wrap = match param {
+            GL_REPEAT => Some(Wrap::Repeat),
+            GL_CLAMP | GL_CLAMP_TO_EDGE => Some(Wrap::Clamp),
+            _ => None,
+        };
+        match name {
+            GL_TEXTURE_MIN_FILTER if (GL_NEAREST_MIPMAP_NEAREST..=GL_LINEAR_MIPMAP_LINEAR).contains(&param) => {
+                texture.min_linear = param == GL_LINEAR_MIPMAP_NEAREST || param == GL_LINEAR_MIPMAP_LINEAR;
+            }
+            GL_TEXTURE_MIN_FILTER | GL_TEXTURE_MAG_FILTER if param == GL_NEAREST || param == GL_LINEAR => {
+                let linear = param == GL_LINEAR;
+                if name == GL_TEXTURE_MIN_FILTER {
+                    texture.min_linear = linear;
+                } else {
+                    texture.mag_linear = linear;
+                }
+            }
+            GL_TEXTURE_WRAP_S if wrap.is_some() => texture.wrap_s = wrap.unwrap_or(Wrap::Repeat),
+            GL_TEXTURE_WRAP_T if wrap.is_some() => texture.wrap_t = wrap.unwrap_or(Wrap::Repeat),
+            _ => return Err(GL_INVALID_ENUM),
+        }
+        Ok(())
+    });
+}
+
+/// glTexParameterf - glTexParameteri with a float
+#[no_mangle]
+pub extern "C" fn glTexParameterf(target: GLenum, name: GLenum, param: GLfloat) {
+    glTexParameteri(target, name, param as GLint);
+}
+
+/// glTexEnvi - How textures combine with fragment colors
+#[no_mangle]
+pub extern "C" fn glTexEnvi(target: GLenum, name: GLenum, param: GLint) {
+    command(|context| {
+        let mode = param as GLenum;
+        if target != GL_TEXTURE_ENV || name != GL_TEXTURE_ENV_MODE {
+            return Err(GL_INVALID_ENUM);
+        }
+        if ![GL_MODULATE, GL_DECAL, GL_BLEND, GL_REPLACE, GL_ADD].contains(&mode) {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.env_mode = mode;
+        Ok(())
+    });
+}
+
+/// glTexEnvf - glTexEnvi with a float
+#[no_mangle]
+pub extern "C" fn glTexEnvf(target: GLenum, name: GLenum, param: GLfloat) {
+    glTexEnvi(target, name, param as GLint);
+}
+
+/// glTexEnvfv - glTexEnvf, or the environment color GL_BLEND blends toward
+#[no_mangle]
+pub extern "C" fn glTexEnvfv(target: GLenum, name: GLenum, params: *const GLfloat) {
+    if name != GL_TEXTURE_ENV_COLOR {
+        if let Some([param]) = read_array(params) {
+            glTexEnvf(target, name, param);
+        }
+        return;
+    }
+    let Some(color) = read_array::<GLfloat, 4>(params) else {
+        return;
+    };
+    command(|context| {
+        if target != GL_TEXTURE_ENV {
+            return Err(GL_INVALID_ENUM);
+        }
+        context.env_color = color.map(|c| c.clamp(0.0, 1.0));
+        Ok(())
+    });
+}
+
+/// Draw a textured, blended quad through a context as an app would, and give
+/// back the color read at its middle
+pub fn self_test(width: i32, height: i32) -> Result<u32, GLenum> {
+    let hdc = crate::win32::gdi::GetDC(Handle::NULL);
+    let descriptor = pixel_format_descriptor();
+    let format = ChoosePixelFormat(hdc, &descriptor);
+    let context = if SetPixelFormat(hdc, format, &descriptor) != 0 { wglCreateContext(hdc) } else { Handle::NULL };
+    if context == Handle::NULL || wglMakeCurrent(hdc, context) == 0 {
+        crate::win32::gdi::ReleaseDC(Handle::NULL, hdc);
+        return Err(GL_INVALID_OPERATION);
+    }
+
+    // A 2x2 checkerboard of red and white, modulated by green at half
+    // alpha over blue
+    let texels: [u8; 16] = [255, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 255];
+    let mut texture = 0;
+    glViewport(0, 0, width, height);
+    glClearColor(0.0, 0.0, 1.0, 1.0);
+    glClear(GL_COLOR_BUFFER_BIT | GL_DEPTH_BUFFER_BIT);
+    glGenTextures(1, &mut texture);
+    glBindTexture(GL_TEXTURE_2D, texture);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MIN_FILTER, GL_NEAREST as GLint);
+    glTexParameteri(GL_TEXTURE_2D, GL_TEXTURE_MAG_FILTER, GL_NEAREST as GLint);
+    glTexImage2D(GL_TEXTURE_2D, 0, GL_RGBA as GLint, 2, 2, 0, GL_RGBA, GL_UNSIGNED_BYTE, texels.as_ptr().cast());
+    glEnable(GL_TEXTURE_2D);
+    glEnable(GL_BLEND);
+    glBlendFunc(GL_SRC_ALPHA, GL_ONE_MINUS_SRC_ALPHA);
+    glMatrixMode(GL_PROJECTION);
+    glLoadIdentity();
+    glFrustum(-1.0, 1.0, -1.0, 1.0, 1.0, 10.0);
+    glMatrixMode(GL_MODELVIEW);
+    glLoadIdentity();
+    glTranslatef(0.0, 0.0, -2.0);
+    glColor4f(0.0, 1.0, 0.0, 0.5);
+    glBegin(GL_QUADS);
+    for (s, t, x, y) in [(0.0, 0.0, -1.0, -1.0), (1.0, 0.0, 1.0, -1.0), (1.0, 1.0, 1.0, 1.0), (0.0, 1.0, -1.0, 1.0)] {
+        glTexCoord2f(s, t);
+        glVertex3f(x, y, 0.0);
+    }
+    glEnd();
+
+    let mut pixel = [0u8; 4];
+    glReadPixels(width / 2, height / 2, 1, 1, GL_BGRA, GL_UNSIGNED_BYTE, pixel.as_mut_ptr().cast());
+    let error = glGetError();
+    wglSwapBuffers(hdc);
+    glDeleteTextures(1, &texture);
+    wglMakeCurrent(Handle::NULL, Handle::NULL);
+    wglDeleteContext(context);
+    crate::win32::gdi::ReleaseDC(Handle::NULL, hdc);
+    if error != GL_NO_ERROR {
+        return Err(error);
+    }
+    Ok(u32::from_le_bytes(pixel))
+}
@@ -0,0 +1,95 @@
+//! Desktop windows for the 3D APIs
+//!
+//! Win32 windows have no pixels of their own yet, so Direct3D and OpenGL
+//! show each frame in a desktop window opened for the purpose. Its client
+//! area is the frame's size, and it takes the title of the Win32 window
+//! the app drew for.
+
+use alloc::string::String;
+
+use crate::gpu::raster::RenderTarget;
+use crate::graphics::framebuffer::FramebufferOps;
+use crate::graphics::window::{Window, WindowFlags, WindowId, WINDOW_MANAGER};
+use crate::win32::Handle;
+
+// Where a framed window first goes on the desktop
+const WINDOW_ORIGIN: (i32, i32) = (48, 48);
+
+/// The client area of a Win32 window, if it has one
+pub fn client_size(hwnd: Handle) -> Option<(u32, u32)> {
+    let rect = crate::win32::window::WINDOW_MANAGER.lock().client_rect(hwnd)?;
+    let (width, height) = (rect.width().max(0) as u32, rect.height().max(0) as u32);
+    (width > 0 && height > 0).then_some((width, height))
+}
+
+/// Open a window whose client area is `width` by `height`: framed, or bare
+/// at the corner of the screen for full-screen drawing
+pub fn open(hwnd: Handle, default_title: &str, width: u32, height: u32, framed: bool) -> Option<WindowId> {
+    let title = crate::win32::window::WINDOW_MANAGER.lock().get_window_text(hwnd);
+    let title = title.filter(|title| !title.is_empty()).unwrap_or_else(|| String::from(default_title));
+    let mut manager = WINDOW_MANAGER.lock();
+    let manager = manager.as_mut()?;
+    let (flags, (x, y)) = if framed {
+        let frame = WindowFlags::VISIBLE
+            | WindowFlags::MOVABLE
+            | WindowFlags::CLOSABLE
+            | WindowFlags::MINIMIZABLE
+            | WindowFlags::HAS_TITLE_BAR
+            | WindowFlags::HAS_BORDER;
+        (frame, WINDOW_ORIGIN)
+    } else {
+        (WindowFlags::VISIBLE, (0, 0))
+    };
+    let id = manager.create_window_with_params(title, x, y, width, height, flags);
+    fit(manager.get_window_mut(id)?, width, height);
+    Some(id)
+}
+
+/// Make the client area `width` by `height`
+pub fn resize(id: WindowId, width: u32, height: u32) {
+    if let Some(window) = WINDOW_MANAGER.lock().as_mut().and_then(|manager| manager.get_window_mut(id)) {
+        fit(window, width, height);
+    }
+}
+
+// Size a window so its client area is `width` by `height`, and draw its
+// frame
+fn fit(window: &mut Window, width: u32, height: u32) {
+    let extra_width = window.rect.width - window.client_rect.width;
+    let extra_height = window.rect.height - window.client_rect.height;
+    window.resize(width + extra_width, height + extra_height);
+    window.paint();
+}
+
+/// Copy a frame into the client area and have the desktop redrawn; with
+/// `opaque`, the frame's alpha is ignored
+pub fn show(id: WindowId, frame: &RenderTarget, opaque: bool) {
+    let mut manager = WINDOW_MANAGER.lock();
+    let Some(window) = manager.as_mut().and_then(|manager| manager.get_window_mut(id)) else {
+        return;
+    };
+    let left = (window.client_rect.x - window.rect.x).max(0) as usize;
+    let top = (window.client_rect.y - window.rect.y).max(0) as usize;
+    let pitch = window.framebuffer.width();
+    let rows = window.framebuffer.height().saturating_sub(top).min(frame.height);
+    let columns = pitch.saturating_sub(left).min(frame.width);
+    let alpha = if opaque { 0xFF00_0000 } else { 0 };
+    let pixels = window.framebuffer.buffer_mut();
+    for y in 0..rows {
+        let from = &frame.color[y * frame.width..][..columns];
+        let to = &mut pixels[(top + y) * pitch + left..][..columns];
+        for (to, from) in to.iter_mut().zip(from) {
+            *to = from | alpha;
+        }
+    }
+    drop(manager);
+    crate::graphics::compositor::request_redraw();
+}
+
+/// Close a window opened here
+pub fn close(id: WindowId) {
+    if let Some(manager) = WINDOW_MANAGER.lock().as_mut() {
+        manager.destroy_window(id);
+    }
+    crate::graphics::compositor::request_redraw();
+}
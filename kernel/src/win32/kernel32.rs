@@ -1,6 +1,20 @@
 use super::*;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::ffi::CStr;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::fs::file_ops::{self, FileMode};
+use crate::fs::{notify, FileSystemError, FsControl, FsControlReply, StreamInfo};
+use super::resource::{self, ResourceName};
+use crate::nt::wdm::types::{ctl_code, METHOD_BUFFERED, METHOD_NEITHER};
+use crate::memory::mmap::{self, Backing};
+use crate::memory::page_cache::{self, FileKey};
 use crate::process::executor::EXECUTOR;
+use crate::process::fork::{self, SpawnError};
+use crate::time::clocksource::now_ns;
 
 /// CreateProcessA - Create a new process (ANSI version)
 #[no_mangle]
@@ -40,24 +54,31 @@ pub extern "C" fn CreateProcessA(
         }
     };
 
+    let command_line = if command_line.is_null() {
+        String::from(app_name)
+    } else {
+        match unsafe { CStr::from_ptr(command_line as *const i8) }.to_str() {
+            Ok(s) => String::from(s),
+            Err(_) => return fail(ERROR_INVALID_PARAMETER, 0),
+        }
+    };
+
     // Log the process creation attempt
     crate::println!("CreateProcessA: Starting {}", app_name);
+    // Windows would ask before running a download; here it is only noted
+    if let Some(zone) = zone_of(app_name).filter(|&zone| zone >= URLZONE_INTERNET) {
+        crate::pr_warn!("CreateProcessA: {} was downloaded from zone {}", app_name, zone);
+    }
     
-    // Create a dummy process for now
-    // In a real implementation, this would:
-    // 1. Load the executable file
-    // 2. Parse PE/ELF format
-    // 3. Create process via EXECUTOR
-    // 4. Set up initial thread
-    // 5. Handle environment and startup info
-    
-    let process_id = {
-        static mut NEXT_PID: u32 = 1000;
-        unsafe {
-            let pid = NEXT_PID;
-            NEXT_PID += 1;
-            pid
-        }
+    // Inheritable handles go to the child only when asked for
+    let parent = EXECUTOR.lock().get_current_pid();
+    let process_id = match fork::spawn(parent, app_name, command_line, inherit_handles != 0) {
+        Ok(pid) => pid,
+        Err(SpawnError::NotFound) => return fail(ERROR_FILE_NOT_FOUND, 0),
+        Err(SpawnError::AccessDenied) => return fail(ERROR_ACCESS_DENIED, 0),
+        Err(SpawnError::BadImage) => return fail(ERROR_BAD_EXE_FORMAT, 0),
+        Err(SpawnError::NoMemory) => return fail(ERROR_NOT_ENOUGH_MEMORY, 0),
+        Err(SpawnError::NoSuchProcess) => return fail(ERROR_INVALID_PARAMETER, 0),
     };
     
     let thread_id = process_id + 1000; // Simple thread ID generation
@@ -75,19 +96,21 @@ pub extern "C" fn CreateProcessA(
     1 // TRUE - success
 }
 
-// Thread-local storage for last error (simplified with static)
+// Fallback last error for threads without a TEB
 static mut LAST_ERROR: DWORD = 0;
 
 /// GetLastError - Get the last error code
 #[no_mangle]
 pub extern "C" fn GetLastError() -> DWORD {
-    unsafe { LAST_ERROR }
+    super::thread::last_error().unwrap_or(unsafe { LAST_ERROR })
 }
 
 /// SetLastError - Set the last error code
 #[no_mangle]
 pub extern "C" fn SetLastError(error: DWORD) {
-    unsafe { LAST_ERROR = error; }
+    if !super::thread::set_last_error(error) {
+        unsafe { LAST_ERROR = error; }
+    }
 }
 
 /// CloseHandle - Close an object handle
@@ -96,10 +119,43 @@ pub extern "C" fn CloseHandle(handle: HANDLE) -> BOOL {
     if handle == Handle::INVALID || handle == Handle::NULL {
         return 0; // FALSE
     }
+    if let Some(section) = SECTIONS.lock().remove(&handle.0) {
+        // Views keep the section's pages until they are unmapped
+        if let FileKey::Anonymous(_) = section.key {
+            page_cache::release_anonymous(&section.key);
+        }
+        return 1;
+    }
+    if file_ops::owns(handle.0 as usize) {
+        return file_ops::sys_close(handle.0 as i32).is_ok() as BOOL;
+    }
+    if EVENTS.lock().remove(&handle.0).is_some() {
+        return 1;
+    }
+    if CHANGE_NOTIFICATIONS.lock().contains_key(&handle.0) {
+        return FindCloseChangeNotification(handle);
+    }
     // Placeholder implementation
     1 // TRUE
 }
 
+const HANDLE_FLAG_INHERIT: DWORD = 0x1;
+
+/// SetHandleInformation - Make a handle inheritable or not
+#[no_mangle]
+pub extern "C" fn SetHandleInformation(handle: HANDLE, mask: DWORD, flags: DWORD) -> BOOL {
+    if !file_ops::owns(handle.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    if mask & HANDLE_FLAG_INHERIT != 0 {
+        let inherit = flags & HANDLE_FLAG_INHERIT != 0;
+        if file_ops::set_cloexec(handle.0 as i32, !inherit).is_err() {
+            return fail(ERROR_INVALID_HANDLE, 0);
+        }
+    }
+    1 // TRUE
+}
+
 /// GetCurrentProcessId - Get current process identifier
 #[no_mangle]
 pub extern "C" fn GetCurrentProcessId() -> DWORD {
@@ -110,14 +166,20 @@ pub extern "C" fn GetCurrentProcessId() -> DWORD {
 /// GetCurrentThreadId - Get current thread identifier
 #[no_mangle]
 pub extern "C" fn GetCurrentThreadId() -> DWORD {
-    // Return a dummy thread ID for now
-    1
+    crate::process::thread::THREAD_MANAGER
+        .lock()
+        .get_current_thread()
+        .map_or(1, |id| id.0)
 }
 
 /// ExitProcess - Terminate the current process
 #[no_mangle]
 pub extern "C" fn ExitProcess(exit_code: DWORD) -> ! {
     crate::println!("Process exiting with code: {}", exit_code);
+    let current = crate::process::PROCESS_MANAGER.lock().current_process;
+    if let Some(process_id) = current {
+        super::loader::detach_process(process_id);
+    }
     crate::hlt_loop();
 }
 
@@ -132,8 +194,63 @@ pub extern "C" fn Sleep(milliseconds: DWORD) {
 /// GetTickCount - Get system uptime in milliseconds
 #[no_mangle]
 pub extern "C" fn GetTickCount() -> DWORD {
-    // Placeholder implementation - return a dummy value
-    12345
+    crate::timer::TIMER.lock().get_uptime_ms() as DWORD
+}
+
+/// GetSystemTime - Current date and time in UTC
+#[no_mangle]
+pub extern "C" fn GetSystemTime(system_time: *mut SystemTime) {
+    if !system_time.is_null() {
+        unsafe { *system_time = crate::time::now_utc().into(); }
+    }
+}
+
+/// GetLocalTime - Current date and time in the local time zone
+#[no_mangle]
+pub extern "C" fn GetLocalTime(system_time: *mut SystemTime) {
+    if !system_time.is_null() {
+        unsafe { *system_time = crate::time::now_local().into(); }
+    }
+}
+
+/// GetSystemTimeAsFileTime - Current UTC time as a FILETIME
+#[no_mangle]
+pub extern "C" fn GetSystemTimeAsFileTime(file_time: *mut FileTime) {
+    if !file_time.is_null() {
+        unsafe { *file_time = crate::time::filetime_now().into(); }
+    }
+}
+
+fn set_time(system_time: *const SystemTime, local: bool) -> BOOL {
+    if system_time.is_null() {
+        unsafe { SetLastError(87); } // ERROR_INVALID_PARAMETER
+        return 0;
+    }
+    if !crate::security::accounts::caller_is_admin() {
+        unsafe { SetLastError(1314); } // ERROR_PRIVILEGE_NOT_HELD
+        return 0;
+    }
+    let time = unsafe { *system_time }.to_date_time();
+    let result = if local { crate::time::set_local(&time) } else { crate::time::set_utc(&time) };
+    match result {
+        Ok(()) => 1,
+        Err(_) => {
+            unsafe { SetLastError(87); } // ERROR_INVALID_PARAMETER
+            0
+        }
+    }
+}
+
+/// SetSystemTime - Set the clock from a UTC time; needs an administrator
+#[no_mangle]
+pub extern "C" fn SetSystemTime(system_time: *const SystemTime) -> BOOL {
+    set_time(system_time, false)
+}
+
+/// SetLocalTime - Set the clock from a local time; needs an administrator
+#[no_mangle]
+pub extern "C" fn SetLocalTime(system_time: *const SystemTime) -> BOOL {
+    set_time(system_time, true)
 }
 
 /// VirtualAlloc - Reserve or commit memory pages
@@ -192,46 +309,125 @@ pub extern "C" fn VirtualFree(
     1 // TRUE
 }
 
-/// GetModuleHandleA - Get module handle
+/// GetModuleHandleA - Get handle of a loaded module
 #[no_mangle]
 pub extern "C" fn GetModuleHandleA(module_name: LPCSTR) -> Handle {
     if module_name.is_null() {
-        // Return handle to current process
-        return Handle(0x400000); // Standard base address
+        // The executable image of the calling process
+        let process_id = crate::process::PROCESS_MANAGER
+            .lock()
+            .current_process
+            .unwrap_or(crate::process::ProcessId(1));
+        let base = super::thread::WIN32_THREADS
+            .lock()
+            .process_mut(process_id)
+            .map_or(0, |process| process.peb().image_base_address);
+        return Handle(base);
+    }
+
+    let name = unsafe {
+        match CStr::from_ptr(module_name as *const i8).to_str() {
+            Ok(s) => s,
+            Err(_) => return Handle::NULL,
+        }
+    };
+
+    match super::loader::module_handle(name) {
+        Some(handle) => handle,
+        None => {
+            SetLastError(super::loader::ERROR_MOD_NOT_FOUND);
+            Handle::NULL
+        }
     }
-    
-    // For now, return a dummy handle
-    Handle(0x400000)
+}
+
+/// GetModuleHandleW - Get handle of a loaded module (wide version)
+#[no_mangle]
+pub extern "C" fn GetModuleHandleW(module_name: LPCWSTR) -> Handle {
+    if module_name.is_null() {
+        return GetModuleHandleA(core::ptr::null());
+    }
+    let Some(name) = (unsafe { super::advapi32::wide_to_string(module_name) }) else {
+        return Handle::NULL;
+    };
+    match super::loader::module_handle(&name) {
+        Some(handle) => handle,
+        None => fail(super::loader::ERROR_MOD_NOT_FOUND, Handle::NULL),
+    }
+}
+
+/// GetModuleFileNameA - Get the full path of a loaded module
+#[no_mangle]
+pub extern "C" fn GetModuleFileNameA(module: Handle, filename: LPSTR, size: DWORD) -> DWORD {
+    if filename.is_null() || size == 0 {
+        SetLastError(87); // ERROR_INVALID_PARAMETER
+        return 0;
+    }
+
+    let Some(path) = super::loader::module_path(module) else {
+        SetLastError(super::loader::ERROR_MOD_NOT_FOUND);
+        return 0;
+    };
+
+    // Truncates like Windows, always NUL-terminating
+    let len = path.len().min(size as usize - 1);
+    unsafe {
+        core::ptr::copy_nonoverlapping(path.as_ptr(), filename, len);
+        *filename.add(len) = 0;
+    }
+    len as DWORD
+}
+
+/// GetModuleFileNameW - Get the full path of a loaded module (wide
+/// version); the size and result count UTF-16 units
+#[no_mangle]
+pub extern "C" fn GetModuleFileNameW(module: Handle, filename: LPWSTR, size: DWORD) -> DWORD {
+    if filename.is_null() || size == 0 {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+
+    let Some(path) = super::loader::module_path(module) else {
+        return fail(super::loader::ERROR_MOD_NOT_FOUND, 0);
+    };
+
+    let units: Vec<u16> = path.encode_utf16().collect();
+    let len = units.len().min(size as usize - 1);
+    unsafe {
+        core::ptr::copy_nonoverlapping(units.as_ptr(), filename, len);
+        *filename.add(len) = 0;
+    }
+    len as DWORD
 }
 
 /// GetProcAddress - Get function address from module
 #[no_mangle]
 pub extern "C" fn GetProcAddress(module: Handle, proc_name: LPCSTR) -> *const u8 {
+    use super::loader::ImportRef;
+
     if proc_name.is_null() {
+        SetLastError(87); // ERROR_INVALID_PARAMETER
         return core::ptr::null();
     }
-    
-    let name = unsafe {
-        match CStr::from_ptr(proc_name as *const i8).to_str() {
-            Ok(s) => s,
-            Err(_) => return core::ptr::null(),
-        }
+
+    // Values below 0x10000 are ordinals rather than name pointers
+    let result = if (proc_name as usize) < 0x10000 {
+        super::loader::get_proc_address(module, ImportRef::Ordinal(proc_name as usize as u16))
+    } else {
+        let name = unsafe {
+            match CStr::from_ptr(proc_name as *const i8).to_str() {
+                Ok(s) => s,
+                Err(_) => return core::ptr::null(),
+            }
+        };
+        super::loader::get_proc_address(module, ImportRef::Name(name))
     };
-    
-    // Return addresses of our implemented functions
-    match name {
-        "CreateProcessA" => CreateProcessA as *const u8,
-        "GetLastError" => GetLastError as *const u8,
-        "SetLastError" => SetLastError as *const u8,
-        "CloseHandle" => CloseHandle as *const u8,
-        "ExitProcess" => ExitProcess as *const u8,
-        "Sleep" => Sleep as *const u8,
-        "GetTickCount" => GetTickCount as *const u8,
-        "VirtualAlloc" => VirtualAlloc as *const u8,
-        "VirtualFree" => VirtualFree as *const u8,
-        "GetModuleHandleA" => GetModuleHandleA as *const u8,
-        "GetProcAddress" => GetProcAddress as *const u8,
-        _ => core::ptr::null(),
+
+    match result {
+        Ok(address) => address as *const u8,
+        Err(error) => {
+            SetLastError(error);
+            core::ptr::null()
+        }
     }
 }
 
@@ -239,7 +435,7 @@ pub extern "C" fn GetProcAddress(module: Handle, proc_name: LPCSTR) -> *const u8
 #[no_mangle]
 pub extern "C" fn LoadLibraryA(filename: LPCSTR) -> Handle {
     if filename.is_null() {
-        unsafe { SetLastError(87); } // ERROR_INVALID_PARAMETER
+        SetLastError(87); // ERROR_INVALID_PARAMETER
         return Handle::NULL;
     }
     
@@ -253,16 +449,24 @@ pub extern "C" fn LoadLibraryA(filename: LPCSTR) -> Handle {
         }
     };
     
-    crate::println!("LoadLibrary: {}", name);
-    
-    // For now, return a dummy handle for known DLLs
-    match name.to_lowercase().as_str() {
-        "kernel32.dll" | "kernel32" => Handle(0x77000000),
-        "ntdll.dll" | "ntdll" => Handle(0x77100000),
-        "user32.dll" | "user32" => Handle(0x77200000),
-        "gdi32.dll" | "gdi32" => Handle(0x77300000),
-        _ => {
-            unsafe { SetLastError(2); } // ERROR_FILE_NOT_FOUND
+    load_library(name)
+}
+
+/// LoadLibraryW - Load a DLL (wide version)
+#[no_mangle]
+pub extern "C" fn LoadLibraryW(filename: LPCWSTR) -> Handle {
+    match unsafe { super::advapi32::wide_to_string(filename) } {
+        Some(name) => load_library(&name),
+        None => fail(ERROR_INVALID_PARAMETER, Handle::NULL),
+    }
+}
+
+fn load_library(name: &str) -> Handle {
+    match super::loader::load_library(name) {
+        Ok(handle) => handle,
+        Err(error) => {
+            crate::println!("LoadLibrary: failed to load {} (error {})", name, error);
+            SetLastError(error);
             Handle::NULL
         }
     }
@@ -272,12 +476,97 @@ pub extern "C" fn LoadLibraryA(filename: LPCSTR) -> Handle {
 #[no_mangle]
 pub extern "C" fn FreeLibrary(module: Handle) -> BOOL {
     if module == Handle::NULL || module == Handle::INVALID {
-        unsafe { SetLastError(6); } // ERROR_INVALID_HANDLE
+        SetLastError(ERROR_INVALID_HANDLE);
         return 0;
     }
     
-    // Placeholder implementation
-    1 // TRUE
+    match super::loader::free_library(module) {
+        Ok(()) => 1,
+        Err(error) => {
+            SetLastError(error);
+            0
+        }
+    }
+}
+
+/// DisableThreadLibraryCalls - Stop thread attach/detach notifications for a DLL
+#[no_mangle]
+pub extern "C" fn DisableThreadLibraryCalls(module: Handle) -> BOOL {
+    if super::loader::disable_thread_calls(module) {
+        1
+    } else {
+        SetLastError(ERROR_INVALID_HANDLE);
+        0
+    }
+}
+
+// Resources. FindResource's handle points at the resource's
+// IMAGE_RESOURCE_DATA_ENTRY in the mapped image and LoadResource's at its
+// data, both of which stay while the module is loaded.
+
+fn find_resource(module: Handle, name: Option<ResourceName>, kind: Option<ResourceName>) -> Handle {
+    let (Some(name), Some(kind)) = (name, kind) else {
+        return fail(ERROR_INVALID_PARAMETER, Handle::NULL);
+    };
+    let found = resource::module_image(module)
+        .and_then(|image| Ok(image.base() + image.find(&kind, &name, None)?.entry as u64));
+    match found {
+        Ok(address) => Handle(address),
+        Err(error) => fail(error, Handle::NULL),
+    }
+}
+
+// The data of a resource FindResource found in `module`
+fn resource_data(module: Handle, resource: Handle) -> Result<&'static [u8], DWORD> {
+    let image = resource::module_image(module)?;
+    let entry = resource.0.checked_sub(image.base()).ok_or(ERROR_INVALID_HANDLE)?;
+    image.resource_at(entry as usize).ok_or(ERROR_INVALID_HANDLE)
+}
+
+/// FindResourceA - Find a resource of a module, NULL being the executable,
+/// by name and type
+#[no_mangle]
+pub extern "C" fn FindResourceA(module: Handle, name: LPCSTR, kind: LPCSTR) -> Handle {
+    unsafe { find_resource(module, ResourceName::from_ansi(name), ResourceName::from_ansi(kind)) }
+}
+
+/// FindResourceW - Find a resource of a module by name and type (wide
+/// version)
+#[no_mangle]
+pub extern "C" fn FindResourceW(module: Handle, name: LPCWSTR, kind: LPCWSTR) -> Handle {
+    unsafe { find_resource(module, ResourceName::from_wide(name), ResourceName::from_wide(kind)) }
+}
+
+/// LoadResource - Get the data of a resource found with FindResource
+#[no_mangle]
+pub extern "C" fn LoadResource(module: Handle, resource: Handle) -> Handle {
+    match resource_data(module, resource) {
+        Ok(data) => Handle(data.as_ptr() as u64),
+        Err(error) => fail(error, Handle::NULL),
+    }
+}
+
+/// LockResource - Get a pointer to loaded resource data, which is where
+/// LoadResource left it
+#[no_mangle]
+pub extern "C" fn LockResource(data: Handle) -> *const u8 {
+    data.0 as *const u8
+}
+
+/// SizeofResource - Get the size of a resource found with FindResource
+#[no_mangle]
+pub extern "C" fn SizeofResource(module: Handle, resource: Handle) -> DWORD {
+    match resource_data(module, resource) {
+        Ok(data) => data.len() as DWORD,
+        Err(error) => fail(error, 0),
+    }
+}
+
+/// FreeResource - Nothing to free, as resources go with their module;
+/// returns FALSE as Windows does
+#[no_mangle]
+pub extern "C" fn FreeResource(_data: Handle) -> BOOL {
+    0
 }
 
 /// WriteFile - Write to file or device
@@ -287,7 +576,7 @@ pub extern "C" fn WriteFile(
     buffer: *const u8,
     bytes_to_write: DWORD,
     bytes_written: *mut DWORD,
-    overlapped: *mut u8,
+    overlapped: *mut Overlapped,
 ) -> BOOL {
     if buffer.is_null() {
         unsafe { SetLastError(87); } // ERROR_INVALID_PARAMETER
@@ -312,7 +601,27 @@ pub extern "C" fn WriteFile(
         return 1; // TRUE
     }
     
-    // File system write would go here
+    if file_ops::owns(file.0 as usize) {
+        let data = unsafe { core::slice::from_raw_parts(buffer, bytes_to_write as usize) };
+        if let Some(overlapped) = unsafe { overlapped.as_mut() } {
+            let result = file_ops::sys_pwrite(file.0 as i32, data, overlapped.offset()).map_err(io_error);
+            return complete_overlapped(overlapped, result, bytes_written);
+        }
+        return match file_ops::sys_write(file.0 as i32, data) {
+            Ok(written) => {
+                if !bytes_written.is_null() {
+                    unsafe { *bytes_written = written as DWORD; }
+                }
+                1 // TRUE
+            }
+            Err(FileSystemError::Locked) => fail(ERROR_LOCK_VIOLATION, 0),
+            Err(_) => {
+                SetLastError(ERROR_ACCESS_DENIED);
+                0 // FALSE
+            }
+        };
+    }
+    
     unsafe { SetLastError(6); } // ERROR_INVALID_HANDLE
     0 // FALSE
 }
@@ -324,7 +633,7 @@ pub extern "C" fn ReadFile(
     buffer: *mut u8,
     bytes_to_read: DWORD,
     bytes_read: *mut DWORD,
-    overlapped: *mut u8,
+    overlapped: *mut Overlapped,
 ) -> BOOL {
     if buffer.is_null() {
         unsafe { SetLastError(87); } // ERROR_INVALID_PARAMETER
@@ -340,7 +649,27 @@ pub extern "C" fn ReadFile(
         return 1; // TRUE
     }
     
-    // File system read would go here
+    if file_ops::owns(file.0 as usize) {
+        let data = unsafe { core::slice::from_raw_parts_mut(buffer, bytes_to_read as usize) };
+        if let Some(overlapped) = unsafe { overlapped.as_mut() } {
+            let result = read_at(file, data, overlapped.offset());
+            return complete_overlapped(overlapped, result, bytes_read);
+        }
+        return match file_ops::sys_read(file.0 as i32, data) {
+            Ok(read) => {
+                if !bytes_read.is_null() {
+                    unsafe { *bytes_read = read as DWORD; }
+                }
+                1 // TRUE
+            }
+            Err(FileSystemError::Locked) => fail(ERROR_LOCK_VIOLATION, 0),
+            Err(_) => {
+                SetLastError(ERROR_ACCESS_DENIED);
+                0 // FALSE
+            }
+        };
+    }
+    
     unsafe { SetLastError(6); } // ERROR_INVALID_HANDLE
     0 // FALSE
 }
@@ -371,10 +700,1080 @@ pub extern "C" fn CreateFileA(
         }
     };
     
-    // For now, return a dummy file handle
-    // In a real implementation, this would open the file through VFS
-    crate::println!("CreateFileA: {}", name);
-    Handle(1000) // Dummy file handle
+    create_file(name, desired_access, security_attributes, creation_disposition)
+}
+
+/// CreateFileW - Create or open file (wide version)
+#[no_mangle]
+pub extern "C" fn CreateFileW(
+    filename: LPCWSTR,
+    desired_access: DWORD,
+    _share_mode: DWORD,
+    security_attributes: *mut u8,
+    creation_disposition: DWORD,
+    _flags_and_attributes: DWORD,
+    _template_file: Handle,
+) -> Handle {
+    match unsafe { super::advapi32::wide_to_string(filename) } {
+        Some(name) => create_file(&name, desired_access, security_attributes, creation_disposition),
+        None => fail(ERROR_INVALID_PARAMETER, Handle::INVALID),
+    }
+}
+
+fn create_file(name: &str, desired_access: DWORD, security_attributes: *mut u8, creation_disposition: DWORD) -> Handle {
+    let mut mode = FileMode::empty();
+    if desired_access & (GENERIC_READ | GENERIC_ALL) != 0 {
+        mode |= FileMode::READ;
+    }
+    if desired_access & (GENERIC_WRITE | GENERIC_ALL) != 0 {
+        mode |= FileMode::WRITE;
+    }
+    // CREATE_NEW, CREATE_ALWAYS and OPEN_ALWAYS
+    if matches!(creation_disposition, 1 | 2 | 4) {
+        mode |= FileMode::CREATE;
+    }
+    // Handles are only inherited when created inheritable
+    let attributes = security_attributes as *const SecurityAttributes;
+    if attributes.is_null() || unsafe { (*attributes).inherit_handle } == 0 {
+        mode |= FileMode::CLOEXEC;
+    }
+    
+    match file_ops::sys_open(name, mode.bits()) {
+        Ok(fd) => Handle(fd as u64),
+        Err(crate::fs::FileSystemError::PermissionDenied) => {
+            SetLastError(ERROR_ACCESS_DENIED);
+            Handle::INVALID
+        }
+        Err(_) => {
+            SetLastError(ERROR_FILE_NOT_FOUND);
+            Handle::INVALID
+        }
+    }
+}
+
+const GENERIC_READ: DWORD = 0x8000_0000;
+const GENERIC_WRITE: DWORD = 0x4000_0000;
+const GENERIC_ALL: DWORD = 0x1000_0000;
+
+const PAGE_READONLY: DWORD = 0x02;
+const PAGE_READWRITE: DWORD = 0x04;
+const PAGE_WRITECOPY: DWORD = 0x08;
+const PAGE_EXECUTE_READ: DWORD = 0x20;
+const PAGE_EXECUTE_READWRITE: DWORD = 0x40;
+const PAGE_EXECUTE_WRITECOPY: DWORD = 0x80;
+
+const FILE_MAP_COPY: DWORD = 0x01;
+const FILE_MAP_WRITE: DWORD = 0x02;
+const FILE_MAP_READ: DWORD = 0x04;
+const FILE_MAP_EXECUTE: DWORD = 0x20;
+
+const ERROR_INVALID_PARAMETER: DWORD = 87;
+const ERROR_BAD_EXE_FORMAT: DWORD = 193;
+const ERROR_FILE_INVALID: DWORD = 1006;
+const ERROR_MAPPED_ALIGNMENT: DWORD = 1132;
+
+// Views start on this boundary in the file, as on Windows
+const ALLOCATION_GRANULARITY: u64 = 0x1_0000;
+
+// Section handles are numbered clear of file descriptors
+const FIRST_SECTION_HANDLE: u64 = 0x1_0000;
+
+// A file mapping object: what it maps, how big it is, and what its views
+// may do
+struct Section {
+    key: FileKey,
+    size: u64,
+    writable: bool,
+    executable: bool,
+}
+
+static SECTIONS: Mutex<BTreeMap<u64, Section>> = Mutex::new(BTreeMap::new());
+
+fn fail<T>(error: DWORD, value: T) -> T {
+    SetLastError(error);
+    value
+}
+
+/// CreateFileMappingA - Create a section backed by a file, or by the
+/// paging file when `file` is INVALID_HANDLE_VALUE
+#[no_mangle]
+pub extern "C" fn CreateFileMappingA(
+    file: Handle,
+    _attributes: *mut u8,
+    protect: DWORD,
+    maximum_size_high: DWORD,
+    maximum_size_low: DWORD,
+    _name: LPCSTR,
+) -> Handle {
+    // Names are not looked up yet, so every call makes a new section
+    let (writable, executable) = match protect & 0xFF {
+        PAGE_READONLY | PAGE_WRITECOPY => (false, false),
+        PAGE_READWRITE => (true, false),
+        PAGE_EXECUTE_READ | PAGE_EXECUTE_WRITECOPY => (false, true),
+        PAGE_EXECUTE_READWRITE => (true, true),
+        _ => return fail(ERROR_INVALID_PARAMETER, Handle::NULL),
+    };
+    let mut size = (maximum_size_high as u64) << 32 | maximum_size_low as u64;
+    
+    let key = if file == Handle::INVALID {
+        if size == 0 {
+            return fail(ERROR_INVALID_PARAMETER, Handle::NULL);
+        }
+        page_cache::create_anonymous(size)
+    } else {
+        let Some((key, readable, can_write)) = file_ops::mappable(file.0 as usize) else {
+            return fail(ERROR_INVALID_HANDLE, Handle::NULL);
+        };
+        if !readable || (writable && !can_write) {
+            return fail(ERROR_ACCESS_DENIED, Handle::NULL);
+        }
+        let file_size = page_cache::size(&key).unwrap_or(0);
+        if size == 0 {
+            size = file_size;
+        }
+        if size == 0 {
+            return fail(ERROR_FILE_INVALID, Handle::NULL);
+        }
+        // A writable section larger than its file grows the file
+        if size > file_size {
+            if !writable || page_cache::extend(&key, size).is_err() {
+                return fail(ERROR_ACCESS_DENIED, Handle::NULL);
+            }
+        }
+        key
+    };
+    
+    let mut sections = SECTIONS.lock();
+    let handle = (FIRST_SECTION_HANDLE..).find(|handle| !sections.contains_key(handle)).unwrap_or(FIRST_SECTION_HANDLE);
+    sections.insert(handle, Section { key, size, writable, executable });
+    Handle(handle)
+}
+
+/// MapViewOfFile - Map part of a section into the address space
+#[no_mangle]
+pub extern "C" fn MapViewOfFile(
+    mapping: Handle,
+    desired_access: DWORD,
+    offset_high: DWORD,
+    offset_low: DWORD,
+    bytes_to_map: usize,
+) -> *mut u8 {
+    let Some(pid) = crate::process::PROCESS_MANAGER.lock().current_process.map(|pid| pid.0) else {
+        return fail(ERROR_INVALID_HANDLE, core::ptr::null_mut());
+    };
+    let (key, size, writable, executable) = match SECTIONS.lock().get(&mapping.0) {
+        Some(section) => (section.key.clone(), section.size, section.writable, section.executable),
+        None => return fail(ERROR_INVALID_HANDLE, core::ptr::null_mut()),
+    };
+    
+    let offset = (offset_high as u64) << 32 | offset_low as u64;
+    if offset % ALLOCATION_GRANULARITY != 0 {
+        return fail(ERROR_MAPPED_ALIGNMENT, core::ptr::null_mut());
+    }
+    let length = match bytes_to_map as u64 {
+        0 => size.saturating_sub(offset),
+        length => length,
+    };
+    if length == 0 || offset + length > size {
+        return fail(ERROR_ACCESS_DENIED, core::ptr::null_mut());
+    }
+    
+    // FILE_MAP_COPY alone asks for a private copy-on-write view
+    let write = desired_access & FILE_MAP_WRITE != 0;
+    let copy = desired_access & (FILE_MAP_READ | FILE_MAP_WRITE) == 0 && desired_access & FILE_MAP_COPY != 0;
+    let execute = desired_access & FILE_MAP_EXECUTE != 0;
+    if (write && !writable) || (execute && !executable) {
+        return fail(ERROR_ACCESS_DENIED, core::ptr::null_mut());
+    }
+    let mut prot = mmap::PROT_READ;
+    if write || copy {
+        prot |= mmap::PROT_WRITE;
+    }
+    if execute {
+        prot |= mmap::PROT_EXEC;
+    }
+    let flags = if copy { mmap::MAP_PRIVATE } else { mmap::MAP_SHARED };
+    
+    match mmap::map(pid, VirtAddr::new(0), length, prot, flags, Backing::Object(key, offset)) {
+        Ok(start) => start.as_u64() as *mut u8,
+        Err(_) => fail(ERROR_NOT_ENOUGH_MEMORY, core::ptr::null_mut()),
+    }
+}
+
+/// UnmapViewOfFile - Unmap a view, given its base address
+#[no_mangle]
+pub extern "C" fn UnmapViewOfFile(base_address: *const u8) -> BOOL {
+    let Some(pid) = crate::process::PROCESS_MANAGER.lock().current_process.map(|pid| pid.0) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    let base = VirtAddr::new(base_address as u64);
+    match mmap::mapping_at(pid, base) {
+        Some(view) if view.start == base => {
+            mmap::unmap(pid, view.start, view.end - view.start).is_ok() as BOOL
+        }
+        _ => fail(ERROR_INVALID_PARAMETER, 0),
+    }
+}
+
+/// FlushViewOfFile - Write a view's changes to its file; zero bytes
+/// means to the end of the view
+#[no_mangle]
+pub extern "C" fn FlushViewOfFile(base_address: *const u8, bytes_to_flush: usize) -> BOOL {
+    let Some(pid) = crate::process::PROCESS_MANAGER.lock().current_process.map(|pid| pid.0) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    let address = VirtAddr::new(base_address as u64);
+    let Some(view) = mmap::mapping_at(pid, address) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    let start = address.align_down(4096u64);
+    let end = match bytes_to_flush {
+        0 => view.end,
+        bytes => (address + bytes as u64).min(view.end),
+    };
+    match mmap::sync(pid, start, end - start, mmap::MS_SYNC) {
+        Ok(()) => 1,
+        Err(_) => fail(ERROR_ACCESS_DENIED, 0),
+    }
+}
+
+const FILE_DEVICE_FILE_SYSTEM: DWORD = 0x09;
+const FILE_ANY_ACCESS: DWORD = 0;
+const FILE_READ_ACCESS: DWORD = 1;
+const FILE_WRITE_ACCESS: DWORD = 2;
+
+const FSCTL_GET_COMPRESSION: DWORD = ctl_code(FILE_DEVICE_FILE_SYSTEM, 15, METHOD_BUFFERED, FILE_ANY_ACCESS);
+const FSCTL_SET_COMPRESSION: DWORD =
+    ctl_code(FILE_DEVICE_FILE_SYSTEM, 16, METHOD_BUFFERED, FILE_READ_ACCESS | FILE_WRITE_ACCESS);
+const FSCTL_SET_SPARSE: DWORD = ctl_code(FILE_DEVICE_FILE_SYSTEM, 49, METHOD_BUFFERED, FILE_ANY_ACCESS);
+const FSCTL_SET_ZERO_DATA: DWORD = ctl_code(FILE_DEVICE_FILE_SYSTEM, 50, METHOD_BUFFERED, FILE_WRITE_ACCESS);
+const FSCTL_QUERY_ALLOCATED_RANGES: DWORD = ctl_code(FILE_DEVICE_FILE_SYSTEM, 51, METHOD_NEITHER, FILE_READ_ACCESS);
+
+const COMPRESSION_FORMAT_NONE: u16 = 0;
+const COMPRESSION_FORMAT_DEFAULT: u16 = 1;
+const COMPRESSION_FORMAT_LZNT1: u16 = 2;
+
+const ERROR_INVALID_FUNCTION: DWORD = 1;
+const ERROR_INSUFFICIENT_BUFFER: DWORD = 122;
+const ERROR_MORE_DATA: DWORD = 234;
+
+// The request a file system control code and its input buffer make
+fn fs_control_request(code: DWORD, input: &[u8]) -> Result<FsControl, DWORD> {
+    let u64_at = |at: usize| input.get(at..at + 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+    match code {
+        FSCTL_GET_COMPRESSION => Ok(FsControl::GetCompression),
+        FSCTL_SET_COMPRESSION => match input.get(..2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]])) {
+            Some(COMPRESSION_FORMAT_NONE) => Ok(FsControl::SetCompression(false)),
+            Some(COMPRESSION_FORMAT_DEFAULT | COMPRESSION_FORMAT_LZNT1) => Ok(FsControl::SetCompression(true)),
+            _ => Err(ERROR_INVALID_PARAMETER),
+        },
+        // With no FILE_SET_SPARSE_BUFFER the file is made sparse
+        FSCTL_SET_SPARSE => Ok(FsControl::SetSparse(input.first().map_or(true, |&set| set != 0))),
+        FSCTL_SET_ZERO_DATA => match (u64_at(0), u64_at(8)) {
+            (Some(offset), Some(end)) if offset <= end => Ok(FsControl::SetZeroData { offset, end }),
+            _ => Err(ERROR_INVALID_PARAMETER),
+        },
+        FSCTL_QUERY_ALLOCATED_RANGES => match (u64_at(0), u64_at(8)) {
+            (Some(offset), Some(length)) => Ok(FsControl::QueryAllocatedRanges { offset, length }),
+            _ => Err(ERROR_INVALID_PARAMETER),
+        },
+        _ => Err(ERROR_INVALID_FUNCTION),
+    }
+}
+
+/// DeviceIoControl - Send a control code to a device. Files take the
+/// FSCTL_ codes for compression, sparse files and allocated ranges; the
+/// call is always synchronous.
+#[no_mangle]
+pub extern "C" fn DeviceIoControl(
+    device: Handle,
+    io_control_code: DWORD,
+    in_buffer: *const u8,
+    in_buffer_size: DWORD,
+    out_buffer: *mut u8,
+    out_buffer_size: DWORD,
+    bytes_returned: *mut DWORD,
+    _overlapped: *mut u8,
+) -> BOOL {
+    if !file_ops::owns(device.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let input = if in_buffer.is_null() {
+        &[][..]
+    } else {
+        unsafe { core::slice::from_raw_parts(in_buffer, in_buffer_size as usize) }
+    };
+    let request = match fs_control_request(io_control_code, input) {
+        Ok(request) => request,
+        Err(error) => return fail(error, 0),
+    };
+
+    let reply = match file_ops::control(device.0 as usize, request) {
+        Ok(reply) => reply,
+        Err(FileSystemError::PermissionDenied) => return fail(ERROR_ACCESS_DENIED, 0),
+        Err(FileSystemError::NotSupported) => return fail(ERROR_INVALID_FUNCTION, 0),
+        Err(_) => return fail(ERROR_INVALID_PARAMETER, 0),
+    };
+    let (output, complete) = match reply {
+        FsControlReply::Done => (Vec::new(), true),
+        FsControlReply::Compressed(compressed) => {
+            let format = if compressed { COMPRESSION_FORMAT_LZNT1 } else { COMPRESSION_FORMAT_NONE };
+            if out_buffer_size < 2 {
+                return fail(ERROR_INSUFFICIENT_BUFFER, 0);
+            }
+            (format.to_le_bytes().to_vec(), true)
+        }
+        // As many FILE_ALLOCATED_RANGE_BUFFERs as fit
+        FsControlReply::Ranges(ranges) => {
+            let fit = (out_buffer_size as usize / 16).min(ranges.len());
+            let output = ranges[..fit].iter().flat_map(|&(offset, length)| {
+                offset.to_le_bytes().into_iter().chain(length.to_le_bytes())
+            });
+            (output.collect(), fit == ranges.len())
+        }
+    };
+
+    if !output.is_empty() {
+        unsafe { core::ptr::copy_nonoverlapping(output.as_ptr(), out_buffer, output.len()) };
+    }
+    if !bytes_returned.is_null() {
+        unsafe { *bytes_returned = output.len() as DWORD };
+    }
+    if !complete {
+        return fail(ERROR_MORE_DATA, 0);
+    }
+    1
+}
+
+const ERROR_HANDLE_EOF: DWORD = 38;
+
+const FIND_STREAM_INFO_STANDARD: DWORD = 0;
+// MAX_PATH plus room for the ":" and ":$DATA" around a stream's name
+const MAX_STREAM_NAME: usize = 260 + 36;
+
+// Stream searches are numbered clear of sections
+const FIRST_FIND_HANDLE: u64 = 0x2_0000;
+
+/// WIN32_FIND_STREAM_DATA
+#[repr(C)]
+pub struct Win32FindStreamData {
+    pub stream_size: i64,
+    pub stream_name: [u16; MAX_STREAM_NAME],
+}
+
+// The streams each FindFirstStreamW search has yet to hand back
+static STREAM_SEARCHES: Mutex<BTreeMap<u64, Vec<StreamInfo>>> = Mutex::new(BTreeMap::new());
+
+// Fill in a WIN32_FIND_STREAM_DATA, named as `:name:$DATA`
+fn fill_stream_data(stream: &StreamInfo, data: *mut Win32FindStreamData) {
+    let name: Vec<u16> = alloc::format!(":{}:$DATA", stream.name).encode_utf16().collect();
+    let data = unsafe { &mut *data };
+    let len = name.len().min(MAX_STREAM_NAME - 1);
+    data.stream_size = stream.size as i64;
+    data.stream_name[..len].copy_from_slice(&name[..len]);
+    data.stream_name[len] = 0;
+}
+
+/// FindFirstStreamW - Start listing a file's data streams, its own data
+/// as `::$DATA` first
+#[no_mangle]
+pub extern "C" fn FindFirstStreamW(
+    file_name: LPCWSTR,
+    info_level: DWORD,
+    find_stream_data: *mut Win32FindStreamData,
+    _flags: DWORD,
+) -> Handle {
+    if info_level != FIND_STREAM_INFO_STANDARD || find_stream_data.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, Handle::INVALID);
+    }
+    let Some(path) = (unsafe { super::advapi32::wide_to_string(file_name) }) else {
+        return fail(ERROR_INVALID_PARAMETER, Handle::INVALID);
+    };
+    let mut streams = match crate::fs::vfs::VFS.lock().list_streams(&path) {
+        Ok(streams) => streams,
+        Err(error) => return fail(super::advapi32::file_error(error), Handle::INVALID),
+    };
+    if streams.is_empty() {
+        return fail(ERROR_HANDLE_EOF, Handle::INVALID);
+    }
+
+    fill_stream_data(&streams.remove(0), find_stream_data);
+    let mut searches = STREAM_SEARCHES.lock();
+    let handle = (FIRST_FIND_HANDLE..).find(|handle| !searches.contains_key(handle)).unwrap_or(FIRST_FIND_HANDLE);
+    streams.reverse();
+    searches.insert(handle, streams);
+    Handle(handle)
+}
+
+/// FindNextStreamW - The next stream of a FindFirstStreamW search;
+/// ERROR_HANDLE_EOF once there are no more
+#[no_mangle]
+pub extern "C" fn FindNextStreamW(find_stream: Handle, find_stream_data: *mut Win32FindStreamData) -> BOOL {
+    if find_stream_data.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    match STREAM_SEARCHES.lock().get_mut(&find_stream.0).map(|streams| streams.pop()) {
+        Some(Some(stream)) => {
+            fill_stream_data(&stream, find_stream_data);
+            1
+        }
+        Some(None) => fail(ERROR_HANDLE_EOF, 0),
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+/// FindClose - End a search
+#[no_mangle]
+pub extern "C" fn FindClose(find_file: Handle) -> BOOL {
+    match STREAM_SEARCHES.lock().remove(&find_file.0) {
+        Some(_) => 1,
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+// Overlapped I/O
+//
+// A read or write given an OVERLAPPED goes at the offset it holds rather
+// than at the file position. It runs through the page cache in the
+// caller's context, so it has finished by the time the call returns: the
+// call returns as Windows does for I/O that completes at once, with the
+// OVERLAPPED filled in and its event set. ReadFileEx and WriteFileEx
+// queue their completion routine on the calling thread instead, which runs
+// it the next time it waits alertably.
+
+/// OVERLAPPED
+#[repr(C)]
+pub struct Overlapped {
+    /// The transfer's NTSTATUS
+    pub internal: usize,
+    /// Bytes transferred
+    pub internal_high: usize,
+    pub offset: DWORD,
+    pub offset_high: DWORD,
+    pub event: Handle,
+}
+
+impl Overlapped {
+    fn offset(&self) -> u64 {
+        (self.offset_high as u64) << 32 | self.offset as u64
+    }
+}
+
+/// LPOVERLAPPED_COMPLETION_ROUTINE
+pub type OverlappedCompletionRoutine = extern "C" fn(error: DWORD, transferred: DWORD, overlapped: *mut Overlapped);
+
+const STATUS_SUCCESS: usize = 0;
+const STATUS_END_OF_FILE: usize = 0xC000_0011;
+const STATUS_ACCESS_DENIED: usize = 0xC000_0022;
+const STATUS_FILE_LOCK_CONFLICT: usize = 0xC000_0054;
+
+// A finished transfer waiting for its thread to wait alertably: the
+// routine, its error and count, and the OVERLAPPED's address
+type Completion = (OverlappedCompletionRoutine, DWORD, DWORD, usize);
+
+static COMPLETIONS: Mutex<BTreeMap<DWORD, Vec<Completion>>> = Mutex::new(BTreeMap::new());
+
+fn io_error(error: FileSystemError) -> DWORD {
+    match error {
+        FileSystemError::Locked => ERROR_LOCK_VIOLATION,
+        _ => ERROR_ACCESS_DENIED,
+    }
+}
+
+// A read at `offset`. Reading from the end of the file is an error, as it
+// is for overlapped reads on Windows.
+fn read_at(file: Handle, buffer: &mut [u8], offset: u64) -> Result<usize, DWORD> {
+    match file_ops::sys_pread(file.0 as i32, buffer, offset).map_err(io_error)? {
+        0 if !buffer.is_empty() => Err(ERROR_HANDLE_EOF),
+        read => Ok(read),
+    }
+}
+
+fn status_of(error: DWORD) -> usize {
+    match error {
+        ERROR_SUCCESS => STATUS_SUCCESS,
+        ERROR_HANDLE_EOF => STATUS_END_OF_FILE,
+        ERROR_LOCK_VIOLATION => STATUS_FILE_LOCK_CONFLICT,
+        _ => STATUS_ACCESS_DENIED,
+    }
+}
+
+fn error_of(status: usize) -> DWORD {
+    match status {
+        STATUS_SUCCESS => ERROR_SUCCESS,
+        STATUS_END_OF_FILE => ERROR_HANDLE_EOF,
+        STATUS_FILE_LOCK_CONFLICT => ERROR_LOCK_VIOLATION,
+        _ => ERROR_ACCESS_DENIED,
+    }
+}
+
+// Record a finished transfer in its OVERLAPPED, giving its error and count
+fn finish(overlapped: &mut Overlapped, result: Result<usize, DWORD>) -> (DWORD, DWORD) {
+    let (error, count) = match result {
+        Ok(count) => (ERROR_SUCCESS, count),
+        Err(error) => (error, 0),
+    };
+    overlapped.internal = status_of(error);
+    overlapped.internal_high = count;
+    (error, count as DWORD)
+}
+
+// Finish a transfer and set the OVERLAPPED's event, for the call's result
+fn complete_overlapped(overlapped: &mut Overlapped, result: Result<usize, DWORD>, transferred: *mut DWORD) -> BOOL {
+    let (error, count) = finish(overlapped, result);
+    if overlapped.event != Handle::NULL {
+        SetEvent(overlapped.event);
+    }
+    if !transferred.is_null() {
+        unsafe { *transferred = count };
+    }
+    if error != ERROR_SUCCESS {
+        return fail(error, 0);
+    }
+    1
+}
+
+// Finish a transfer for ReadFileEx or WriteFileEx. One that failed fails
+// the call; one that went ahead is reported through the routine.
+fn queue_completion(
+    overlapped: &mut Overlapped,
+    result: Result<usize, DWORD>,
+    completion: OverlappedCompletionRoutine,
+) -> BOOL {
+    let (error, count) = finish(overlapped, result);
+    if error != ERROR_SUCCESS {
+        return fail(error, 0);
+    }
+    let queued = (completion, error, count, overlapped as *mut Overlapped as usize);
+    COMPLETIONS.lock().entry(GetCurrentThreadId()).or_default().push(queued);
+    1
+}
+
+// Run the completion routines queued on the calling thread; whether any ran
+fn run_completions() -> bool {
+    let queued = COMPLETIONS.lock().remove(&GetCurrentThreadId()).unwrap_or_default();
+    for &(routine, error, count, overlapped) in &queued {
+        routine(error, count, overlapped as *mut Overlapped);
+    }
+    !queued.is_empty()
+}
+
+/// ReadFileEx - Read at the OVERLAPPED's offset, calling `completion` when
+/// the thread next waits alertably
+#[no_mangle]
+pub extern "C" fn ReadFileEx(
+    file: Handle,
+    buffer: *mut u8,
+    bytes_to_read: DWORD,
+    overlapped: *mut Overlapped,
+    completion: Option<OverlappedCompletionRoutine>,
+) -> BOOL {
+    let (Some(overlapped), Some(completion)) = (unsafe { overlapped.as_mut() }, completion) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    if buffer.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let data = unsafe { core::slice::from_raw_parts_mut(buffer, bytes_to_read as usize) };
+    let result = read_at(file, data, overlapped.offset());
+    queue_completion(overlapped, result, completion)
+}
+
+/// WriteFileEx - Write at the OVERLAPPED's offset, calling `completion`
+/// when the thread next waits alertably
+#[no_mangle]
+pub extern "C" fn WriteFileEx(
+    file: Handle,
+    buffer: *const u8,
+    bytes_to_write: DWORD,
+    overlapped: *mut Overlapped,
+    completion: Option<OverlappedCompletionRoutine>,
+) -> BOOL {
+    let (Some(overlapped), Some(completion)) = (unsafe { overlapped.as_mut() }, completion) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    if buffer.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let data = unsafe { core::slice::from_raw_parts(buffer, bytes_to_write as usize) };
+    let result = file_ops::sys_pwrite(file.0 as i32, data, overlapped.offset()).map_err(io_error);
+    queue_completion(overlapped, result, completion)
+}
+
+/// GetOverlappedResult - The outcome of an overlapped transfer. Transfers
+/// have finished by the time the call that made them returns, so there is
+/// never one to wait for.
+#[no_mangle]
+pub extern "C" fn GetOverlappedResult(
+    _file: Handle,
+    overlapped: *const Overlapped,
+    transferred: *mut DWORD,
+    _wait: BOOL,
+) -> BOOL {
+    let Some(overlapped) = (unsafe { overlapped.as_ref() }) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    if !transferred.is_null() {
+        unsafe { *transferred = overlapped.internal_high as DWORD };
+    }
+    match error_of(overlapped.internal) {
+        ERROR_SUCCESS => 1,
+        error => fail(error, 0),
+    }
+}
+
+/// CancelIo - Cancel a file's transfers in flight, of which there are none
+#[no_mangle]
+pub extern "C" fn CancelIo(file: Handle) -> BOOL {
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    1
+}
+
+// Byte-range locks; see fs::locks. They belong to the file handle, and go
+// when it closes.
+
+const LOCKFILE_FAIL_IMMEDIATELY: DWORD = 0x1;
+const LOCKFILE_EXCLUSIVE_LOCK: DWORD = 0x2;
+
+fn dword_pair(low: DWORD, high: DWORD) -> u64 {
+    (high as u64) << 32 | low as u64
+}
+
+fn lock_error(error: FileSystemError) -> DWORD {
+    match error {
+        FileSystemError::Locked => ERROR_LOCK_VIOLATION,
+        FileSystemError::NotFound => ERROR_NOT_LOCKED,
+        _ => ERROR_INVALID_PARAMETER,
+    }
+}
+
+/// LockFile - Lock a range of a file exclusively, failing at once if
+/// another handle holds any of it
+#[no_mangle]
+pub extern "C" fn LockFile(
+    file: Handle,
+    offset_low: DWORD,
+    offset_high: DWORD,
+    length_low: DWORD,
+    length_high: DWORD,
+) -> BOOL {
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let (offset, length) = (dword_pair(offset_low, offset_high), dword_pair(length_low, length_high));
+    match file_ops::sys_lock(file.0 as i32, offset, length, true, false) {
+        Ok(()) => 1,
+        Err(error) => fail(lock_error(error), 0),
+    }
+}
+
+/// LockFileEx - Lock a range of a file from the OVERLAPPED's offset:
+/// shared, or exclusive with LOCKFILE_EXCLUSIVE_LOCK, and waiting for
+/// other handles' locks unless LOCKFILE_FAIL_IMMEDIATELY
+#[no_mangle]
+pub extern "C" fn LockFileEx(
+    file: Handle,
+    flags: DWORD,
+    _reserved: DWORD,
+    length_low: DWORD,
+    length_high: DWORD,
+    overlapped: *mut Overlapped,
+) -> BOOL {
+    let Some(overlapped) = (unsafe { overlapped.as_mut() }) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let exclusive = flags & LOCKFILE_EXCLUSIVE_LOCK != 0;
+    let wait = flags & LOCKFILE_FAIL_IMMEDIATELY == 0;
+    let length = dword_pair(length_low, length_high);
+    let result = file_ops::sys_lock(file.0 as i32, overlapped.offset(), length, exclusive, wait);
+    complete_overlapped(overlapped, result.map(|()| 0).map_err(lock_error), core::ptr::null_mut())
+}
+
+/// UnlockFile - Unlock a range locked with exactly that offset and length
+#[no_mangle]
+pub extern "C" fn UnlockFile(
+    file: Handle,
+    offset_low: DWORD,
+    offset_high: DWORD,
+    length_low: DWORD,
+    length_high: DWORD,
+) -> BOOL {
+    if !file_ops::owns(file.0 as usize) {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    let (offset, length) = (dword_pair(offset_low, offset_high), dword_pair(length_low, length_high));
+    match file_ops::sys_unlock(file.0 as i32, offset, length) {
+        Ok(()) => 1,
+        Err(error) => fail(lock_error(error), 0),
+    }
+}
+
+/// UnlockFileEx - Unlock a range from the OVERLAPPED's offset
+#[no_mangle]
+pub extern "C" fn UnlockFileEx(
+    file: Handle,
+    _reserved: DWORD,
+    length_low: DWORD,
+    length_high: DWORD,
+    overlapped: *mut Overlapped,
+) -> BOOL {
+    let Some(overlapped) = (unsafe { overlapped.as_ref() }) else {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    };
+    let offset = overlapped.offset();
+    UnlockFile(file, offset as DWORD, (offset >> 32) as DWORD, length_low, length_high)
+}
+
+// Events and waits
+//
+// A wait looks at its object every millisecond until it is signalled or
+// the time is up. An alertable one first runs the completion routines
+// queued on the thread, and returns WAIT_IO_COMPLETION if there were any.
+
+const WAIT_OBJECT_0: DWORD = 0;
+const WAIT_IO_COMPLETION: DWORD = 0xC0;
+const WAIT_TIMEOUT: DWORD = 0x102;
+const WAIT_FAILED: DWORD = 0xFFFF_FFFF;
+const INFINITE: DWORD = 0xFFFF_FFFF;
+
+const WAIT_STEP_NS: u64 = 1_000_000;
+
+// Event handles are numbered clear of find handles
+const FIRST_EVENT_HANDLE: u64 = 0x3_0000;
+
+struct Event {
+    manual_reset: bool,
+    signalled: bool,
+}
+
+static EVENTS: Mutex<BTreeMap<u64, Event>> = Mutex::new(BTreeMap::new());
+
+fn create_event(manual_reset: BOOL, initial_state: BOOL) -> Handle {
+    let mut events = EVENTS.lock();
+    let handle = (FIRST_EVENT_HANDLE..).find(|handle| !events.contains_key(handle)).unwrap_or(FIRST_EVENT_HANDLE);
+    events.insert(handle, Event { manual_reset: manual_reset != 0, signalled: initial_state != 0 });
+    Handle(handle)
+}
+
+/// CreateEventA - Create an event. Names are not looked up, so each call
+/// makes a new one.
+#[no_mangle]
+pub extern "C" fn CreateEventA(
+    _attributes: *const SecurityAttributes,
+    manual_reset: BOOL,
+    initial_state: BOOL,
+    _name: LPCSTR,
+) -> Handle {
+    create_event(manual_reset, initial_state)
+}
+
+/// CreateEventW - Create an event (wide version)
+#[no_mangle]
+pub extern "C" fn CreateEventW(
+    _attributes: *const SecurityAttributes,
+    manual_reset: BOOL,
+    initial_state: BOOL,
+    _name: LPCWSTR,
+) -> Handle {
+    create_event(manual_reset, initial_state)
+}
+
+fn set_event_state(event: Handle, signalled: bool) -> BOOL {
+    match EVENTS.lock().get_mut(&event.0) {
+        Some(event) => {
+            event.signalled = signalled;
+            1
+        }
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+/// Signal an event from code that may run in an interrupt, as the sound
+/// mixer does; false if the event table is busy and it should try again
+pub(super) fn signal_event(event: Handle) -> bool {
+    let Some(mut events) = EVENTS.try_lock() else {
+        return false;
+    };
+    if let Some(event) = events.get_mut(&event.0) {
+        event.signalled = true;
+    }
+    true
+}
+
+/// SetEvent - Signal an event
+#[no_mangle]
+pub extern "C" fn SetEvent(event: Handle) -> BOOL {
+    set_event_state(event, true)
+}
+
+/// ResetEvent - Clear an event's signal
+#[no_mangle]
+pub extern "C" fn ResetEvent(event: Handle) -> BOOL {
+    set_event_state(event, false)
+}
+
+// Whether a handle is signalled, taking an auto-reset event's signal; None
+// for a handle that cannot be waited on
+fn try_wait(handle: Handle) -> Option<bool> {
+    if let Some(event) = EVENTS.lock().get_mut(&handle.0) {
+        let signalled = event.signalled;
+        if !event.manual_reset {
+            event.signalled = false;
+        }
+        return Some(signalled);
+    }
+    if let Some(notification) = CHANGE_NOTIFICATIONS.lock().get_mut(&handle.0) {
+        notification.seen = notify::signalled(notification.watch)?;
+        return Some(notification.seen > 0);
+    }
+    // A file is signalled when its I/O is done, which it always is
+    if file_ops::owns(handle.0 as usize) {
+        return Some(true);
+    }
+    let mut exit_code = 0;
+    if super::thread::GetExitCodeThread(handle, &mut exit_code) != 0 {
+        return Some(exit_code != super::thread::STILL_ACTIVE);
+    }
+    None
+}
+
+// When a wait of `milliseconds` ends, or None for INFINITE
+fn wait_deadline(milliseconds: DWORD) -> Option<u64> {
+    (milliseconds != INFINITE).then(|| now_ns() + milliseconds as u64 * 1_000_000)
+}
+
+/// WaitForSingleObjectEx - Wait up to `milliseconds` for an event, change
+/// notification, thread or file to be signalled
+#[no_mangle]
+pub extern "C" fn WaitForSingleObjectEx(handle: Handle, milliseconds: DWORD, alertable: BOOL) -> DWORD {
+    let deadline = wait_deadline(milliseconds);
+    loop {
+        if alertable != 0 && run_completions() {
+            return WAIT_IO_COMPLETION;
+        }
+        match try_wait(handle) {
+            Some(true) => return WAIT_OBJECT_0,
+            Some(false) => {}
+            None => return fail(ERROR_INVALID_HANDLE, WAIT_FAILED),
+        }
+        if deadline.is_some_and(|deadline| now_ns() >= deadline) {
+            return WAIT_TIMEOUT;
+        }
+        crate::time::hrtimer::sleep_ns(WAIT_STEP_NS);
+    }
+}
+
+/// WaitForSingleObject - Wait up to `milliseconds` for a handle to be
+/// signalled
+#[no_mangle]
+pub extern "C" fn WaitForSingleObject(handle: Handle, milliseconds: DWORD) -> DWORD {
+    WaitForSingleObjectEx(handle, milliseconds, 0)
+}
+
+/// SleepEx - Sleep for `milliseconds`; with `alertable`, completion
+/// routines queued on the thread end the sleep early
+#[no_mangle]
+pub extern "C" fn SleepEx(milliseconds: DWORD, alertable: BOOL) -> DWORD {
+    let deadline = wait_deadline(milliseconds);
+    loop {
+        if alertable != 0 && run_completions() {
+            return WAIT_IO_COMPLETION;
+        }
+        if deadline.is_some_and(|deadline| now_ns() >= deadline) {
+            return 0;
+        }
+        crate::time::hrtimer::sleep_ns(WAIT_STEP_NS);
+    }
+}
+
+// Directory change notification; see fs::notify. Each handle has a watch,
+// and the count of changes the last wait on it saw, which
+// FindNextChangeNotification takes back.
+
+// Change notification handles are numbered clear of events
+const FIRST_CHANGE_HANDLE: u64 = 0x4_0000;
+
+struct ChangeNotification {
+    watch: u64,
+    seen: usize,
+}
+
+static CHANGE_NOTIFICATIONS: Mutex<BTreeMap<u64, ChangeNotification>> = Mutex::new(BTreeMap::new());
+
+fn find_first_change(path: &str, watch_subtree: BOOL, filter: DWORD) -> Handle {
+    if filter == 0 || filter & !notify::CHANGE_ALL != 0 {
+        return fail(ERROR_INVALID_PARAMETER, Handle::INVALID);
+    }
+    let watch = match crate::fs::vfs::VFS.lock().watch_directory(path, watch_subtree != 0, filter) {
+        Ok(watch) => watch,
+        Err(error) => return fail(super::advapi32::file_error(error), Handle::INVALID),
+    };
+    let mut notifications = CHANGE_NOTIFICATIONS.lock();
+    let handle =
+        (FIRST_CHANGE_HANDLE..).find(|handle| !notifications.contains_key(handle)).unwrap_or(FIRST_CHANGE_HANDLE);
+    notifications.insert(handle, ChangeNotification { watch, seen: 0 });
+    Handle(handle)
+}
+
+/// FindFirstChangeNotificationA - Watch a directory, and with
+/// `watch_subtree` everything below it, for the changes `filter` names;
+/// the handle is signalled when one happens
+#[no_mangle]
+pub extern "C" fn FindFirstChangeNotificationA(path_name: LPCSTR, watch_subtree: BOOL, filter: DWORD) -> Handle {
+    if path_name.is_null() {
+        return fail(ERROR_INVALID_PARAMETER, Handle::INVALID);
+    }
+    match unsafe { CStr::from_ptr(path_name as *const i8) }.to_str() {
+        Ok(path) => find_first_change(path, watch_subtree, filter),
+        Err(_) => fail(ERROR_INVALID_PARAMETER, Handle::INVALID),
+    }
+}
+
+/// FindFirstChangeNotificationW - Watch a directory (wide version)
+#[no_mangle]
+pub extern "C" fn FindFirstChangeNotificationW(path_name: LPCWSTR, watch_subtree: BOOL, filter: DWORD) -> Handle {
+    match unsafe { super::advapi32::wide_to_string(path_name) } {
+        Some(path) => find_first_change(&path, watch_subtree, filter),
+        None => fail(ERROR_INVALID_PARAMETER, Handle::INVALID),
+    }
+}
+
+/// FindNextChangeNotification - Wait for the next change; any since the
+/// last wait leave the handle signalled
+#[no_mangle]
+pub extern "C" fn FindNextChangeNotification(handle: Handle) -> BOOL {
+    match CHANGE_NOTIFICATIONS.lock().get_mut(&handle.0) {
+        Some(notification) => {
+            notify::rearm(notification.watch, core::mem::take(&mut notification.seen));
+            1
+        }
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+/// FindCloseChangeNotification - Stop watching
+#[no_mangle]
+pub extern "C" fn FindCloseChangeNotification(handle: Handle) -> BOOL {
+    match CHANGE_NOTIFICATIONS.lock().remove(&handle.0) {
+        Some(notification) => {
+            notify::unwatch(notification.watch);
+            1
+        }
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+// URLZONE_INTERNET; URLZONE_UNTRUSTED is 4
+const URLZONE_INTERNET: u32 = 3;
+
+/// The security zone a file was downloaded from, as the Zone.Identifier
+/// stream browsers give a download records it; None for a file without one
+pub fn zone_of(path: &str) -> Option<u32> {
+    let stream = crate::fs::vfs::VFS.lock().read_file(&alloc::format!("{}:Zone.Identifier", path)).ok()?;
+    let text = core::str::from_utf8(&stream).ok()?;
+    let mut in_zone_transfer = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_zone_transfer = line.eq_ignore_ascii_case("[ZoneTransfer]");
+        } else if let Some(zone) = line.strip_prefix("ZoneId=").filter(|_| in_zone_transfer) {
+            return zone.trim().parse().ok();
+        }
+    }
+    None
+}
+
+const ERROR_NOT_LOCKED: DWORD = 158;
+
+// A global memory block. Blocks never move here, so a moveable block's
+// handle is its address, as a fixed one's is, and locking only counts.
+struct GlobalBlock {
+    data: Box<[u8]>,
+    size: usize,
+    locks: u32,
+}
+
+static GLOBALS: Mutex<BTreeMap<u64, GlobalBlock>> = Mutex::new(BTreeMap::new());
+
+/// A new global block holding a copy of `bytes`
+pub(super) fn global_from(bytes: &[u8]) -> Handle {
+    let handle = GlobalAlloc(0, bytes.len());
+    if let Some(block) = GLOBALS.lock().get_mut(&handle.0) {
+        block.data[..bytes.len()].copy_from_slice(bytes);
+    }
+    handle
+}
+
+/// A copy of what a global block holds
+pub(super) fn global_bytes(handle: Handle) -> Option<Vec<u8>> {
+    GLOBALS.lock().get(&handle.0).map(|block| block.data[..block.size].to_vec())
+}
+
+/// GlobalAlloc - Allocate a block of global memory. It is zeroed whether
+/// or not GMEM_ZEROINIT is given, and fixed or moveable are the same.
+#[no_mangle]
+pub extern "C" fn GlobalAlloc(_flags: DWORD, bytes: usize) -> Handle {
+    // Even an empty block has an address of its own
+    let data = alloc::vec![0u8; bytes.max(1)].into_boxed_slice();
+    let handle = data.as_ptr() as u64;
+    GLOBALS.lock().insert(handle, GlobalBlock { data, size: bytes, locks: 0 });
+    Handle(handle)
+}
+
+/// GlobalLock - The address of a global block's memory
+#[no_mangle]
+pub extern "C" fn GlobalLock(memory: Handle) -> *mut u8 {
+    match GLOBALS.lock().get_mut(&memory.0) {
+        Some(block) => {
+            block.locks = block.locks.saturating_add(1);
+            block.data.as_mut_ptr()
+        }
+        None => fail(ERROR_INVALID_HANDLE, core::ptr::null_mut()),
+    }
+}
+
+/// GlobalUnlock - Undo a GlobalLock; TRUE while the block is still locked
+#[no_mangle]
+pub extern "C" fn GlobalUnlock(memory: Handle) -> BOOL {
+    match GLOBALS.lock().get_mut(&memory.0) {
+        Some(block) if block.locks > 0 => {
+            block.locks -= 1;
+            if block.locks == 0 {
+                return fail(ERROR_SUCCESS, 0);
+            }
+            1
+        }
+        Some(_) => fail(ERROR_NOT_LOCKED, 0),
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+/// GlobalSize - The size of a global block, as asked for
+#[no_mangle]
+pub extern "C" fn GlobalSize(memory: Handle) -> usize {
+    match GLOBALS.lock().get(&memory.0) {
+        Some(block) => block.size,
+        None => fail(ERROR_INVALID_HANDLE, 0),
+    }
+}
+
+/// GlobalFree - Free a global block; NULL on success, else the handle
+#[no_mangle]
+pub extern "C" fn GlobalFree(memory: Handle) -> Handle {
+    match GLOBALS.lock().remove(&memory.0) {
+        Some(_) => Handle::NULL,
+        None => fail(ERROR_INVALID_HANDLE, memory),
+    }
 }
 
 /// GetCommandLineA - Get command line string
@@ -0,0 +1,1471 @@
+// Win32 module loader
+//
+// Maps PE DLLs into the calling process's user address space, applies base
+// relocations, resolves
+// imports (following forwarded exports) and runs DllMain notifications.  Each
+// process keeps its own module list, mirrored into the PEB_LDR_DATA lists so
+// code walking PEB->Ldr sees the same modules as GetModuleHandle.
+//
+// The kernel32/user32/gdi32/advapi32 implementations in this crate are
+// registered as built-in modules whose exports point straight at the Rust
+// functions; they have no image and no entry point.
+use super::*;
+use crate::memory::mmap::{self, Backing};
+use crate::process::pe_loader::PeLoader;
+use crate::process::ProcessId;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::marker::PhantomData;
+use core::mem::{offset_of, size_of};
+use core::ops::{Deref, DerefMut};
+use crate::sync::Mutex;
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+
+// DllMain reasons
+pub const DLL_PROCESS_DETACH: DWORD = 0;
+pub const DLL_PROCESS_ATTACH: DWORD = 1;
+pub const DLL_THREAD_ATTACH: DWORD = 2;
+pub const DLL_THREAD_DETACH: DWORD = 3;
+
+// Loader error codes reported through SetLastError
+pub const ERROR_MOD_NOT_FOUND: DWORD = 126;
+pub const ERROR_PROC_NOT_FOUND: DWORD = 127;
+pub const ERROR_BAD_EXE_FORMAT: DWORD = 193;
+pub const ERROR_DLL_INIT_FAILED: DWORD = 1114;
+
+const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D;
+const IMAGE_NT_SIGNATURE: u32 = 0x00004550;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20B;
+const IMAGE_FILE_DLL: u16 = 0x2000;
+
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+const IMAGE_ORDINAL_FLAG64: u64 = 0x8000000000000000;
+
+// LDR_DATA_TABLE_ENTRY flags
+const LDRP_IMAGE_DLL: u32 = 0x00000004;
+const LDRP_ENTRY_PROCESSED: u32 = 0x00004000;
+const LDRP_DONT_CALL_FOR_THREADS: u32 = 0x00040000;
+const LDRP_PROCESS_ATTACH_CALLED: u32 = 0x00080000;
+
+// Forwarder chains longer than this are treated as cycles
+const MAX_FORWARD_DEPTH: usize = 16;
+
+const DLL_SEARCH_PATHS: &[&str] = &["/Windows/System32/", "/Windows/", "/"];
+
+pub type DllEntryPoint = extern "system" fn(HANDLE, DWORD, *mut c_void) -> BOOL;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ListEntry {
+    pub flink: u64,
+    pub blink: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UnicodeString {
+    pub length: u16,
+    pub maximum_length: u16,
+    _padding: u32,
+    pub buffer: u64,
+}
+
+// PEB_LDR_DATA (x64 layout), referenced from PEB->Ldr
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PebLdrData {
+    pub length: u32,
+    pub initialized: u8,
+    _padding: [u8; 3],
+    pub ss_handle: u64,
+    pub in_load_order_module_list: ListEntry,
+    pub in_memory_order_module_list: ListEntry,
+    pub in_initialization_order_module_list: ListEntry,
+    pub entry_in_progress: u64,
+    pub shutdown_in_progress: u8,
+    _padding2: [u8; 7],
+    pub shutdown_thread_id: u64,
+}
+
+// LDR_DATA_TABLE_ENTRY (x64 layout)
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct LdrDataTableEntry {
+    pub in_load_order_links: ListEntry,
+    pub in_memory_order_links: ListEntry,
+    pub in_initialization_order_links: ListEntry,
+    pub dll_base: u64,
+    pub entry_point: u64,
+    pub size_of_image: u32,
+    _padding: u32,
+    pub full_dll_name: UnicodeString,
+    pub base_dll_name: UnicodeString,
+    pub flags: u32,
+    pub load_count: u16,
+    pub tls_index: u16,
+    pub hash_links: ListEntry,
+}
+
+const _: () = {
+    assert!(offset_of!(PebLdrData, in_load_order_module_list) == 0x10);
+    assert!(offset_of!(PebLdrData, in_memory_order_module_list) == 0x20);
+    assert!(offset_of!(PebLdrData, in_initialization_order_module_list) == 0x30);
+    assert!(offset_of!(LdrDataTableEntry, dll_base) == 0x30);
+    assert!(offset_of!(LdrDataTableEntry, size_of_image) == 0x40);
+    assert!(offset_of!(LdrDataTableEntry, full_dll_name) == 0x48);
+    assert!(offset_of!(LdrDataTableEntry, base_dll_name) == 0x58);
+    assert!(offset_of!(LdrDataTableEntry, flags) == 0x68);
+    assert!(offset_of!(LdrDataTableEntry, load_count) == 0x6C);
+};
+
+// Zeroed pages in a process's user address space, unmapped when dropped
+struct UserPages {
+    process_id: ProcessId,
+    base: u64,
+    size: usize,
+}
+
+impl UserPages {
+    fn map(process_id: ProcessId, size: usize, prot: usize) -> Option<Self> {
+        let flags = mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS;
+        let base = mmap::map(process_id.0, VirtAddr::zero(), size as u64, prot, flags, Backing::Anonymous).ok()?;
+        Some(Self { process_id, base: base.as_u64(), size })
+    }
+}
+
+impl Drop for UserPages {
+    fn drop(&mut self) {
+        let _ = mmap::unmap(self.process_id.0, VirtAddr::new(self.base), self.size as u64);
+    }
+}
+
+// Zeroed kernel pages at a random address in the KASLR module area,
+// unmapped and freed when dropped
+struct ModulePages {
+    base: u64,
+    size: usize,
+}
+
+impl ModulePages {
+    fn map(size: usize) -> Option<Self> {
+        use crate::memory::frame_allocator::{self, FRAME_ALLOCATOR};
+        use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+
+        let base = crate::security::kaslr::reserve_module_range(size as u64)?.as_u64();
+        let size = (size + 0xFFF) & !0xFFF;
+        let mut pages = Self { base, size: 0 };
+        let mapper = unsafe { crate::memory::get_mapper() };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        while pages.size < size {
+            let frame = frame_allocator::allocate_frame()?;
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + pages.size as u64));
+            match unsafe { mapper.map_to(page, frame, flags, &mut *FRAME_ALLOCATOR.lock()) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    frame_allocator::deallocate_frame(frame);
+                    return None;
+                }
+            }
+            pages.size += 0x1000;
+        }
+        unsafe { core::ptr::write_bytes(base as *mut u8, 0, size) };
+        Some(pages)
+    }
+}
+
+impl Drop for ModulePages {
+    fn drop(&mut self) {
+        use x86_64::structures::paging::{Mapper, Page, Size4KiB};
+
+        let mapper = unsafe { crate::memory::get_mapper() };
+        for offset in (0..self.size as u64).step_by(0x1000) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(self.base + offset));
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                crate::memory::frame_allocator::deallocate_frame(frame);
+            }
+        }
+        crate::security::kaslr::release_module_range(VirtAddr::new(self.base));
+    }
+}
+
+enum ImagePages {
+    User(UserPages),
+    Module(ModulePages),
+}
+
+// A loader structure in user memory, where code walking PEB->Ldr reads it,
+// followed by `extra` bytes for what it points to
+pub struct UserBox<T> {
+    pages: UserPages,
+    _marker: PhantomData<T>,
+}
+
+impl<T> UserBox<T> {
+    fn new(process_id: ProcessId, value: T, extra: usize) -> Option<Self> {
+        let pages = UserPages::map(process_id, size_of::<T>() + extra, mmap::PROT_READ | mmap::PROT_WRITE)?;
+        unsafe { core::ptr::write(pages.base as *mut T, value) };
+        Some(Self { pages, _marker: PhantomData })
+    }
+
+    fn address(&self) -> u64 {
+        self.pages.base
+    }
+
+    // The bytes after the value
+    fn extra(&self) -> u64 {
+        self.pages.base + size_of::<T>() as u64
+    }
+}
+
+impl<T> Deref for UserBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(self.pages.base as *const T) }
+    }
+}
+
+impl<T> DerefMut for UserBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.pages.base as *mut T) }
+    }
+}
+
+// Page-aligned memory holding a mapped image: a DLL's in its process, a
+// driver's in the kernel module area. The image goes with its pages.
+pub struct ImageMemory {
+    base: u64,
+    size: usize,
+    _pages: ImagePages,
+}
+
+impl ImageMemory {
+    fn map_module(size: usize) -> Option<Self> {
+        let pages = ModulePages::map(size)?;
+        Some(Self { base: pages.base, size, _pages: ImagePages::Module(pages) })
+    }
+
+    fn map(process_id: ProcessId, size: usize) -> Option<Self> {
+        let prot = mmap::PROT_READ | mmap::PROT_WRITE | mmap::PROT_EXEC;
+        let pages = UserPages::map(process_id, size, prot)?;
+        Some(Self { base: pages.base, size, _pages: ImagePages::User(pages) })
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.base as *const u8, self.size) }
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base as *mut u8, self.size) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Export {
+    Address(u64),
+    // "DLL.Function" or "DLL.#ordinal"
+    Forward(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ImportRef<'a> {
+    Name(&'a str),
+    Ordinal(u16),
+}
+
+#[derive(Default)]
+pub struct ExportTable {
+    pub by_name: BTreeMap<String, Export>,
+    pub by_ordinal: BTreeMap<u16, Export>,
+}
+
+pub struct Module {
+    pub name: String,
+    pub path: String,
+    pub base: u64,
+    pub size: u32,
+    pub entry_point: u64,
+    pub exports: ExportTable,
+    // Modules this one holds a reference on (imports and forwarders)
+    pub dependencies: Vec<String>,
+    pub load_count: u32,
+    // Followed by the buffers behind its UNICODE_STRINGs
+    pub ldr_entry: UserBox<LdrDataTableEntry>,
+    image: Option<ImageMemory>,
+}
+
+impl Module {
+    fn new(process_id: ProcessId, name: String, path: String, base: u64, size: u32, entry_point: u64) -> Option<Self> {
+        let full_name: Vec<u16> = path.encode_utf16().chain(Some(0)).collect();
+        let base_name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+        let extra = (full_name.len() + base_name.len()) * 2;
+        let mut ldr_entry = UserBox::new(process_id, LdrDataTableEntry::default(), extra)?;
+        let full_buffer = ldr_entry.extra();
+        let base_buffer = full_buffer + full_name.len() as u64 * 2;
+        unsafe {
+            core::ptr::copy_nonoverlapping(full_name.as_ptr(), full_buffer as *mut u16, full_name.len());
+            core::ptr::copy_nonoverlapping(base_name.as_ptr(), base_buffer as *mut u16, base_name.len());
+        }
+        ldr_entry.dll_base = base;
+        ldr_entry.entry_point = entry_point;
+        ldr_entry.size_of_image = size;
+        ldr_entry.flags = LDRP_IMAGE_DLL;
+        ldr_entry.load_count = 1;
+        ldr_entry.full_dll_name = unicode_string(full_buffer, full_name.len());
+        ldr_entry.base_dll_name = unicode_string(base_buffer, base_name.len());
+
+        Some(Self {
+            name,
+            path,
+            base,
+            size,
+            entry_point,
+            exports: ExportTable::default(),
+            dependencies: Vec::new(),
+            load_count: 1,
+            ldr_entry,
+            image: None,
+        })
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        self.image.is_none()
+    }
+
+    pub fn handle(&self) -> HANDLE {
+        Handle(self.base)
+    }
+
+    fn dll_main(&self) -> Option<DllEntryPoint> {
+        if self.entry_point == 0 {
+            return None;
+        }
+        Some(unsafe { core::mem::transmute::<usize, DllEntryPoint>(self.entry_point as usize) })
+    }
+}
+
+// A UNICODE_STRING over `len` NUL-terminated UTF-16 units at `buffer`
+fn unicode_string(buffer: u64, len: usize) -> UnicodeString {
+    let chars = len.saturating_sub(1);
+    UnicodeString {
+        length: (chars * 2) as u16,
+        maximum_length: (len * 2) as u16,
+        _padding: 0,
+        buffer,
+    }
+}
+
+// Modules loaded into one process
+pub struct ProcessModules {
+    process_id: ProcessId,
+    modules: BTreeMap<String, Module>,
+    load_order: Vec<String>,
+    init_order: Vec<String>,
+    ldr: UserBox<PebLdrData>,
+}
+
+impl ProcessModules {
+    fn new(process_id: ProcessId) -> Option<Self> {
+        let mut ldr = UserBox::new(process_id, PebLdrData::default(), 0)?;
+        ldr.length = size_of::<PebLdrData>() as u32;
+        ldr.initialized = 1;
+        let mut modules = Self {
+            process_id,
+            modules: BTreeMap::new(),
+            load_order: Vec::new(),
+            init_order: Vec::new(),
+            ldr,
+        };
+        modules.relink();
+        Some(modules)
+    }
+
+    pub fn ldr_address(&self) -> u64 {
+        self.ldr.address()
+    }
+
+    pub fn module(&self, name: &str) -> Option<&Module> {
+        self.modules.get(&normalize_module_name(name))
+    }
+
+    pub fn module_by_handle(&self, handle: HANDLE) -> Option<&Module> {
+        self.modules.values().find(|m| m.base == handle.0)
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &Module> {
+        self.load_order.iter().filter_map(|name| self.modules.get(name))
+    }
+
+    /// Load a module and everything it imports. Modules mapped by this call
+    /// are appended to `loaded` in initialization order (dependencies first).
+    pub fn load(&mut self, name: &str, loaded: &mut Vec<String>) -> Result<String, DWORD> {
+        let key = normalize_module_name(name);
+        if let Some(module) = self.modules.get_mut(&key) {
+            module.load_count += 1;
+            module.ldr_entry.load_count = module.load_count.min(0xFFFF) as u16;
+            return Ok(key);
+        }
+
+        if let Some(module) = builtin_module(self.process_id, &key) {
+            self.insert(module?);
+            return Ok(key);
+        }
+
+        let (path, data) = read_dll(name)?;
+        let image = map_image(self.process_id, &data)?;
+        let (base, size, entry) = {
+            let bytes = image.bytes();
+            let headers = parse_headers(bytes)?;
+            let entry = if headers.entry_rva != 0 { image.base + headers.entry_rva as u64 } else { 0 };
+            (image.base, headers.size_of_image, entry)
+        };
+
+        let mut module = Module::new(self.process_id, key.clone(), path, base, size, entry).ok_or(ERROR_NOT_ENOUGH_MEMORY)?;
+        module.exports = parse_exports(image.bytes(), base);
+        module.image = Some(image);
+        // Insert before resolving imports so import cycles terminate
+        self.insert(module);
+
+        if let Err(error) = self.resolve_imports(&key, loaded) {
+            self.release(&key);
+            return Err(error);
+        }
+
+        loaded.push(key.clone());
+        Ok(key)
+    }
+
+    fn insert(&mut self, module: Module) {
+        let key = module.name.clone();
+        self.modules.insert(key.clone(), module);
+        self.load_order.push(key);
+        self.relink();
+    }
+
+    fn resolve_imports(&mut self, key: &str, loaded: &mut Vec<String>) -> Result<(), DWORD> {
+        let imports = {
+            let module = self.modules.get(key).ok_or(ERROR_MOD_NOT_FOUND)?;
+            let image = module.image.as_ref().ok_or(ERROR_BAD_EXE_FORMAT)?;
+            parse_imports(image.bytes())?
+        };
+
+        for import in imports {
+            let dependency = self.load(&import.dll_name, loaded)?;
+            if let Some(module) = self.modules.get_mut(key) {
+                module.dependencies.push(dependency.clone());
+            }
+
+            for (iat_rva, reference) in import.functions {
+                let target = match &reference {
+                    ImportName::Name(name) => ImportRef::Name(name),
+                    ImportName::Ordinal(ordinal) => ImportRef::Ordinal(*ordinal),
+                };
+                let address = self.resolve_export(&dependency, target, key, loaded, 0)?;
+                let module = self.modules.get_mut(key).ok_or(ERROR_MOD_NOT_FOUND)?;
+                let image = module.image.as_mut().ok_or(ERROR_BAD_EXE_FORMAT)?;
+                write_u64(image.bytes_mut(), iat_rva as usize, address)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve an export of `module`, following forwarders. Modules loaded to
+    /// satisfy a forwarder are referenced by `holder`.
+    pub fn resolve_export(
+        &mut self,
+        module: &str,
+        target: ImportRef,
+        holder: &str,
+        loaded: &mut Vec<String>,
+        depth: usize,
+    ) -> Result<u64, DWORD> {
+        if depth > MAX_FORWARD_DEPTH {
+            return Err(ERROR_PROC_NOT_FOUND);
+        }
+
+        let export = {
+            let exports = &self.modules.get(module).ok_or(ERROR_MOD_NOT_FOUND)?.exports;
+            match target {
+                ImportRef::Name(name) => exports.by_name.get(name),
+                ImportRef::Ordinal(ordinal) => exports.by_ordinal.get(&ordinal),
+            }
+            .cloned()
+            .ok_or(ERROR_PROC_NOT_FOUND)?
+        };
+
+        match export {
+            Export::Address(address) => Ok(address),
+            Export::Forward(forward) => {
+                let (dll, function) = forward.rsplit_once('.').ok_or(ERROR_PROC_NOT_FOUND)?;
+                let dependency = self.load(dll, loaded)?;
+                if let Some(holder) = self.modules.get_mut(holder) {
+                    holder.dependencies.push(dependency.clone());
+                }
+                match function.strip_prefix('#') {
+                    Some(ordinal) => {
+                        let ordinal = ordinal.parse::<u16>().map_err(|_| ERROR_PROC_NOT_FOUND)?;
+                        self.resolve_export(&dependency, ImportRef::Ordinal(ordinal), holder, loaded, depth + 1)
+                    }
+                    None => self.resolve_export(&dependency, ImportRef::Name(function), holder, loaded, depth + 1),
+                }
+            }
+        }
+    }
+
+    /// Drop one reference. Returns the modules that reached zero, in the
+    /// order their DLL_PROCESS_DETACH notifications must run.
+    pub fn release(&mut self, key: &str) -> Vec<Module> {
+        let mut unloaded = Vec::new();
+        self.release_into(key, &mut unloaded);
+        self.relink();
+        unloaded
+    }
+
+    fn release_into(&mut self, key: &str, unloaded: &mut Vec<Module>) {
+        let Some(module) = self.modules.get_mut(key) else {
+            return;
+        };
+        module.load_count = module.load_count.saturating_sub(1);
+        module.ldr_entry.load_count = module.load_count.min(0xFFFF) as u16;
+        if module.load_count > 0 {
+            return;
+        }
+
+        let module = match self.modules.remove(key) {
+            Some(module) => module,
+            None => return,
+        };
+        self.load_order.retain(|name| name != key);
+        self.init_order.retain(|name| name != key);
+        let dependencies = module.dependencies.clone();
+        unloaded.push(module);
+        for dependency in dependencies {
+            self.release_into(&dependency, unloaded);
+        }
+    }
+
+    fn mark_initialized(&mut self, key: &str) {
+        if let Some(module) = self.modules.get_mut(key) {
+            module.ldr_entry.flags |= LDRP_ENTRY_PROCESSED | LDRP_PROCESS_ATTACH_CALLED;
+            if !self.init_order.iter().any(|name| name == key) {
+                self.init_order.push(key.to_string());
+            }
+        }
+        self.relink();
+    }
+
+    /// Rebuild the circular PEB loader lists from the module order vectors
+    fn relink(&mut self) {
+        let ldr_base = self.ldr.address();
+        let load_head = ldr_base + offset_of!(PebLdrData, in_load_order_module_list) as u64;
+        let memory_head = ldr_base + offset_of!(PebLdrData, in_memory_order_module_list) as u64;
+        let init_head = ldr_base + offset_of!(PebLdrData, in_initialization_order_module_list) as u64;
+
+        let entry_address = |modules: &BTreeMap<String, Module>, name: &String| {
+            modules.get(name).map(|m| m.ldr_entry.address())
+        };
+        let load: Vec<u64> = self.load_order.iter().filter_map(|n| entry_address(&self.modules, n)).collect();
+        let init: Vec<u64> = self.init_order.iter().filter_map(|n| entry_address(&self.modules, n)).collect();
+
+        let memory_offset = offset_of!(LdrDataTableEntry, in_memory_order_links) as u64;
+        let init_offset = offset_of!(LdrDataTableEntry, in_initialization_order_links) as u64;
+        let load_links: Vec<u64> = load.clone();
+        let memory_links: Vec<u64> = load.iter().map(|e| e + memory_offset).collect();
+        let init_links: Vec<u64> = init.iter().map(|e| e + init_offset).collect();
+
+        unsafe {
+            link_list(load_head, &load_links);
+            link_list(memory_head, &memory_links);
+            link_list(init_head, &init_links);
+        }
+    }
+}
+
+/// Chain `links` into a circular list anchored at `head`
+unsafe fn link_list(head: u64, links: &[u64]) {
+    let head_entry = head as *mut ListEntry;
+    if links.is_empty() {
+        (*head_entry).flink = head;
+        (*head_entry).blink = head;
+        return;
+    }
+    for (i, &link) in links.iter().enumerate() {
+        let entry = link as *mut ListEntry;
+        (*entry).blink = if i == 0 { head } else { links[i - 1] };
+        (*entry).flink = links.get(i + 1).copied().unwrap_or(head);
+    }
+    (*head_entry).flink = links[0];
+    (*head_entry).blink = links[links.len() - 1];
+}
+
+pub struct ModuleLoader {
+    processes: BTreeMap<u32, ProcessModules>,
+}
+
+lazy_static! {
+    pub static ref MODULE_LOADER: Mutex<ModuleLoader> = Mutex::new(ModuleLoader::new());
+}
+
+impl ModuleLoader {
+    pub fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+        }
+    }
+
+    /// Module list for a process; creating it maps PEB_LDR_DATA into the
+    /// process and publishes it as PEB->Ldr
+    pub fn process_mut(&mut self, process_id: ProcessId) -> Result<&mut ProcessModules, DWORD> {
+        if !self.processes.contains_key(&process_id.0) {
+            let modules = ProcessModules::new(process_id).ok_or(ERROR_NOT_ENOUGH_MEMORY)?;
+            let ldr = modules.ldr_address();
+            if let Some(process) = super::thread::WIN32_THREADS.lock().process_mut(process_id) {
+                process.peb_mut().ldr = ldr;
+            }
+            self.processes.insert(process_id.0, modules);
+        }
+        self.processes.get_mut(&process_id.0).ok_or(ERROR_NOT_ENOUGH_MEMORY)
+    }
+
+    pub fn process(&self, process_id: ProcessId) -> Option<&ProcessModules> {
+        self.processes.get(&process_id.0)
+    }
+
+    pub fn remove_process(&mut self, process_id: ProcessId) -> Option<ProcessModules> {
+        self.processes.remove(&process_id.0)
+    }
+}
+
+/// Canonical module key: lower-case base name with an extension
+pub fn normalize_module_name(name: &str) -> String {
+    let base = name.rsplit(['\\', '/']).next().unwrap_or(name);
+    let mut key = base.to_lowercase();
+    if !key.contains('.') {
+        key.push_str(".dll");
+    }
+    key
+}
+
+fn current_process_id() -> ProcessId {
+    crate::process::PROCESS_MANAGER.lock().current_process.unwrap_or(ProcessId(1))
+}
+
+fn read_dll(name: &str) -> Result<(String, Vec<u8>), DWORD> {
+    let mut vfs = crate::fs::vfs::VFS.lock();
+    let unix = name.replace('\\', "/");
+    let unix = match unix.get(1..2) {
+        Some(":") => unix[2..].to_string(),
+        _ => unix,
+    };
+
+    // Explicit paths are used as given; bare names go through the search list
+    if unix.contains('/') {
+        return vfs.read_file(&unix).map(|data| (name.to_string(), data)).map_err(|_| ERROR_MOD_NOT_FOUND);
+    }
+    let file = normalize_module_name(&unix);
+    for dir in DLL_SEARCH_PATHS {
+        let path = format!("{}{}", dir, file);
+        if let Ok(data) = vfs.read_file(&path) {
+            return Ok((format!("C:{}", path.replace('/', "\\")), data));
+        }
+    }
+    Err(ERROR_MOD_NOT_FOUND)
+}
+
+// PE parsing
+
+struct PeHeaders {
+    entry_rva: u32,
+    image_base: u64,
+    size_of_image: u32,
+    size_of_headers: u32,
+    characteristics: u16,
+    directories: [(u32, u32); 16],
+    sections: Vec<PeSection>,
+}
+
+struct PeSection {
+    virtual_address: u32,
+    virtual_size: u32,
+    raw_offset: u32,
+    raw_size: u32,
+}
+
+pub(crate) enum ImportName {
+    Name(String),
+    Ordinal(u16),
+}
+
+pub(crate) struct ImportDescriptor {
+    pub dll_name: String,
+    // (IAT slot RVA, imported symbol)
+    pub functions: Vec<(u32, ImportName)>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, DWORD> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ERROR_BAD_EXE_FORMAT)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, DWORD> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ERROR_BAD_EXE_FORMAT)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, DWORD> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        .ok_or(ERROR_BAD_EXE_FORMAT)
+}
+
+pub(crate) fn write_u64(data: &mut [u8], offset: usize, value: u64) -> Result<(), DWORD> {
+    data.get_mut(offset..offset + 8)
+        .map(|b| b.copy_from_slice(&value.to_le_bytes()))
+        .ok_or(ERROR_BAD_EXE_FORMAT)
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> Result<String, DWORD> {
+    let bytes = data.get(offset..).ok_or(ERROR_BAD_EXE_FORMAT)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(ERROR_BAD_EXE_FORMAT)?;
+    core::str::from_utf8(&bytes[..len])
+        .map(|s| s.to_string())
+        .map_err(|_| ERROR_BAD_EXE_FORMAT)
+}
+
+fn parse_headers(data: &[u8]) -> Result<PeHeaders, DWORD> {
+    if read_u16(data, 0)? != IMAGE_DOS_SIGNATURE {
+        return Err(ERROR_BAD_EXE_FORMAT);
+    }
+    let nt = read_u32(data, 0x3C)? as usize;
+    if read_u32(data, nt)? != IMAGE_NT_SIGNATURE || read_u16(data, nt + 4)? != IMAGE_FILE_MACHINE_AMD64 {
+        return Err(ERROR_BAD_EXE_FORMAT);
+    }
+
+    let section_count = read_u16(data, nt + 6)? as usize;
+    let optional_size = read_u16(data, nt + 20)? as usize;
+    let characteristics = read_u16(data, nt + 22)?;
+    let optional = nt + 24;
+    if read_u16(data, optional)? != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
+        return Err(ERROR_BAD_EXE_FORMAT);
+    }
+
+    let mut directories = [(0u32, 0u32); 16];
+    let directory_count = (read_u32(data, optional + 108)? as usize).min(16);
+    for (i, directory) in directories.iter_mut().enumerate().take(directory_count) {
+        let offset = optional + 112 + i * 8;
+        *directory = (read_u32(data, offset)?, read_u32(data, offset + 4)?);
+    }
+
+    let mut sections = Vec::with_capacity(section_count);
+    let section_table = optional + optional_size;
+    for i in 0..section_count {
+        let offset = section_table + i * 40;
+        sections.push(PeSection {
+            virtual_size: read_u32(data, offset + 8)?,
+            virtual_address: read_u32(data, offset + 12)?,
+            raw_size: read_u32(data, offset + 16)?,
+            raw_offset: read_u32(data, offset + 20)?,
+        });
+    }
+
+    Ok(PeHeaders {
+        entry_rva: read_u32(data, optional + 16)?,
+        image_base: read_u64(data, optional + 24)?,
+        size_of_image: read_u32(data, optional + 56)?,
+        size_of_headers: read_u32(data, optional + 60)?,
+        characteristics,
+        directories,
+        sections,
+    })
+}
+
+/// Copy headers and sections into memory mapped into the process and apply
+/// relocations
+fn map_image(process_id: ProcessId, data: &[u8]) -> Result<ImageMemory, DWORD> {
+    let headers = parse_headers(data)?;
+    if headers.characteristics & IMAGE_FILE_DLL == 0 || headers.size_of_image == 0 {
+        return Err(ERROR_BAD_EXE_FORMAT);
+    }
+    let image = ImageMemory::map(process_id, headers.size_of_image as usize).ok_or(ERROR_NOT_ENOUGH_MEMORY)?;
+    map_sections(data, &headers, image)
+}
+
+/// Map a kernel-mode driver, which is an executable image rather than a
+/// DLL; returns the image and its entry point's RVA
+pub(crate) fn map_driver_image(data: &[u8]) -> Result<(ImageMemory, u32), DWORD> {
+    let headers = parse_headers(data)?;
+    if headers.characteristics & IMAGE_FILE_DLL != 0 || headers.entry_rva == 0 || headers.size_of_image == 0 {
+        return Err(ERROR_BAD_EXE_FORMAT);
+    }
+    let image = ImageMemory::map_module(headers.size_of_image as usize).ok_or(ERROR_NOT_ENOUGH_MEMORY)?;
+    Ok((map_sections(data, &headers, image)?, headers.entry_rva))
+}
+
+fn map_sections(data: &[u8], headers: &PeHeaders, mut image: ImageMemory) -> Result<ImageMemory, DWORD> {
+    let memory = image.bytes_mut();
+
+    let header_len = (headers.size_of_headers as usize).min(data.len()).min(memory.len());
+    memory[..header_len].copy_from_slice(&data[..header_len]);
+
+    for section in &headers.sections {
+        // Raw data beyond VirtualSize is file alignment padding
+        let len = match section.virtual_size {
+            0 => section.raw_size,
+            size => section.raw_size.min(size),
+        } as usize;
+        let src = data
+            .get(section.raw_offset as usize..section.raw_offset as usize + len)
+            .ok_or(ERROR_BAD_EXE_FORMAT)?;
+        let dst = memory
+            .get_mut(section.virtual_address as usize..section.virtual_address as usize + len)
+            .ok_or(ERROR_BAD_EXE_FORMAT)?;
+        dst.copy_from_slice(src);
+    }
+
+    let delta = image.base.wrapping_sub(headers.image_base);
+    if delta != 0 {
+        // Without relocations the image only works at its preferred base
+        let directory = headers.directories[IMAGE_DIRECTORY_ENTRY_BASERELOC];
+        if directory.0 == 0 || directory.1 == 0 {
+            return Err(ERROR_BAD_EXE_FORMAT);
+        }
+        PeLoader::apply_relocations(image.bytes_mut(), directory, delta).map_err(|_| ERROR_BAD_EXE_FORMAT)?;
+    }
+    Ok(image)
+}
+
+fn parse_exports(image: &[u8], base: u64) -> ExportTable {
+    let mut exports = ExportTable::default();
+    let Ok(headers) = parse_headers(image) else {
+        return exports;
+    };
+    let (dir_rva, dir_size) = headers.directories[IMAGE_DIRECTORY_ENTRY_EXPORT];
+    if dir_rva == 0 {
+        return exports;
+    }
+    let dir = dir_rva as usize;
+    let forward_range = dir_rva..dir_rva + dir_size;
+
+    let parse = |exports: &mut ExportTable| -> Result<(), DWORD> {
+        let ordinal_base = read_u32(image, dir + 16)?;
+        let function_count = read_u32(image, dir + 20)? as usize;
+        let name_count = read_u32(image, dir + 24)? as usize;
+        let functions = read_u32(image, dir + 28)? as usize;
+        let names = read_u32(image, dir + 32)? as usize;
+        let ordinals = read_u32(image, dir + 36)? as usize;
+
+        let mut table = Vec::with_capacity(function_count);
+        for i in 0..function_count {
+            let rva = read_u32(image, functions + i * 4)?;
+            let export = if rva == 0 {
+                None
+            } else if forward_range.contains(&rva) {
+                Some(Export::Forward(read_cstr(image, rva as usize)?))
+            } else {
+                Some(Export::Address(base + rva as u64))
+            };
+            if let Some(export) = &export {
+                exports.by_ordinal.insert((ordinal_base as usize + i) as u16, export.clone());
+            }
+            table.push(export);
+        }
+
+        for i in 0..name_count {
+            let name = read_cstr(image, read_u32(image, names + i * 4)? as usize)?;
+            let index = read_u16(image, ordinals + i * 2)? as usize;
+            if let Some(Some(export)) = table.get(index) {
+                exports.by_name.insert(name, export.clone());
+            }
+        }
+        Ok(())
+    };
+    // A malformed export directory leaves whatever parsed cleanly
+    let _ = parse(&mut exports);
+    exports
+}
+
+pub(crate) fn parse_imports(image: &[u8]) -> Result<Vec<ImportDescriptor>, DWORD> {
+    let headers = parse_headers(image)?;
+    let (dir_rva, _) = headers.directories[IMAGE_DIRECTORY_ENTRY_IMPORT];
+    let mut imports = Vec::new();
+    if dir_rva == 0 {
+        return Ok(imports);
+    }
+
+    let mut descriptor = dir_rva as usize;
+    loop {
+        let original_first_thunk = read_u32(image, descriptor)?;
+        let name_rva = read_u32(image, descriptor + 12)?;
+        let first_thunk = read_u32(image, descriptor + 16)?;
+        if name_rva == 0 {
+            break;
+        }
+
+        let lookup = if original_first_thunk != 0 { original_first_thunk } else { first_thunk } as usize;
+        let mut functions = Vec::new();
+        for i in 0.. {
+            let thunk = read_u64(image, lookup + i * 8)?;
+            if thunk == 0 {
+                break;
+            }
+            let reference = if thunk & IMAGE_ORDINAL_FLAG64 != 0 {
+                ImportName::Ordinal(thunk as u16)
+            } else {
+                // Skip the two-byte hint of IMAGE_IMPORT_BY_NAME
+                ImportName::Name(read_cstr(image, (thunk as u32) as usize + 2)?)
+            };
+            functions.push((first_thunk + (i * 8) as u32, reference));
+        }
+
+        imports.push(ImportDescriptor {
+            dll_name: read_cstr(image, name_rva as usize)?,
+            functions,
+        });
+        descriptor += 20;
+    }
+    Ok(imports)
+}
+
+// Built-in modules backed by this crate's Win32 implementation
+
+// An exported function. A static cannot turn a function pointer into an
+// integer, so the tables keep the pointer.
+#[derive(Clone, Copy)]
+struct BuiltinExport(&'static str, *const ());
+
+// Only ever read, and the functions it points at live forever
+unsafe impl Sync for BuiltinExport {}
+
+macro_rules! exports {
+    ($($name:ident => $path:path),* $(,)?) => {{
+        static EXPORTS: &[BuiltinExport] = &[$(BuiltinExport(stringify!($name), $path as *const ())),*];
+        EXPORTS
+    }};
+}
+
+fn builtin_exports(key: &str) -> Option<(u64, &'static [BuiltinExport])> {
+    use super::{advapi32, avicap32, console, dsound, gdi, graphics, kernel32, message, thread, user32, window, xinput};
+
+    let table: (u64, &'static [BuiltinExport]) = match key {
+        "ntdll.dll" => (0x77100000, &[]),
+        "kernel32.dll" => (0x77000000, exports! {
+            CreateProcessA => kernel32::CreateProcessA,
+            GetLastError => kernel32::GetLastError,
+            SetLastError => kernel32::SetLastError,
+            CloseHandle => kernel32::CloseHandle,
+            SetHandleInformation => kernel32::SetHandleInformation,
+            GetCurrentProcessId => kernel32::GetCurrentProcessId,
+            GetCurrentThreadId => kernel32::GetCurrentThreadId,
+            ExitProcess => kernel32::ExitProcess,
+            Sleep => kernel32::Sleep,
+            SleepEx => kernel32::SleepEx,
+            CreateEventA => kernel32::CreateEventA,
+            CreateEventW => kernel32::CreateEventW,
+            SetEvent => kernel32::SetEvent,
+            ResetEvent => kernel32::ResetEvent,
+            WaitForSingleObject => kernel32::WaitForSingleObject,
+            WaitForSingleObjectEx => kernel32::WaitForSingleObjectEx,
+            GetTickCount => kernel32::GetTickCount,
+            GetSystemTime => kernel32::GetSystemTime,
+            GetLocalTime => kernel32::GetLocalTime,
+            GetSystemTimeAsFileTime => kernel32::GetSystemTimeAsFileTime,
+            SetSystemTime => kernel32::SetSystemTime,
+            SetLocalTime => kernel32::SetLocalTime,
+            VirtualAlloc => kernel32::VirtualAlloc,
+            VirtualFree => kernel32::VirtualFree,
+            GetModuleHandleA => kernel32::GetModuleHandleA,
+            GetModuleHandleW => kernel32::GetModuleHandleW,
+            GetModuleFileNameA => kernel32::GetModuleFileNameA,
+            GetModuleFileNameW => kernel32::GetModuleFileNameW,
+            GetProcAddress => kernel32::GetProcAddress,
+            LoadLibraryA => kernel32::LoadLibraryA,
+            LoadLibraryW => kernel32::LoadLibraryW,
+            FreeLibrary => kernel32::FreeLibrary,
+            DisableThreadLibraryCalls => kernel32::DisableThreadLibraryCalls,
+            FindResourceA => kernel32::FindResourceA,
+            FindResourceW => kernel32::FindResourceW,
+            LoadResource => kernel32::LoadResource,
+            LockResource => kernel32::LockResource,
+            SizeofResource => kernel32::SizeofResource,
+            FreeResource => kernel32::FreeResource,
+            WriteFile => kernel32::WriteFile,
+            ReadFile => kernel32::ReadFile,
+            ReadFileEx => kernel32::ReadFileEx,
+            WriteFileEx => kernel32::WriteFileEx,
+            GetOverlappedResult => kernel32::GetOverlappedResult,
+            CancelIo => kernel32::CancelIo,
+            LockFile => kernel32::LockFile,
+            LockFileEx => kernel32::LockFileEx,
+            UnlockFile => kernel32::UnlockFile,
+            UnlockFileEx => kernel32::UnlockFileEx,
+            CreateFileA => kernel32::CreateFileA,
+            CreateFileW => kernel32::CreateFileW,
+            FindFirstChangeNotificationA => kernel32::FindFirstChangeNotificationA,
+            FindFirstChangeNotificationW => kernel32::FindFirstChangeNotificationW,
+            FindNextChangeNotification => kernel32::FindNextChangeNotification,
+            FindCloseChangeNotification => kernel32::FindCloseChangeNotification,
+            CreateFileMappingA => kernel32::CreateFileMappingA,
+            MapViewOfFile => kernel32::MapViewOfFile,
+            UnmapViewOfFile => kernel32::UnmapViewOfFile,
+            FlushViewOfFile => kernel32::FlushViewOfFile,
+            GetCommandLineA => kernel32::GetCommandLineA,
+            GetEnvironmentVariableA => kernel32::GetEnvironmentVariableA,
+            CreateThread => thread::CreateThread,
+            ExitThread => thread::ExitThread,
+            GetExitCodeThread => thread::GetExitCodeThread,
+            SuspendThread => thread::SuspendThread,
+            ResumeThread => thread::ResumeThread,
+            GetCurrentThread => thread::GetCurrentThread,
+            GetThreadId => thread::GetThreadId,
+            SetThreadPriority => thread::SetThreadPriority,
+            GetThreadPriority => thread::GetThreadPriority,
+            SetPriorityClass => thread::SetPriorityClass,
+            GetPriorityClass => thread::GetPriorityClass,
+            SwitchToThread => thread::SwitchToThread,
+            TlsAlloc => thread::TlsAlloc,
+            TlsFree => thread::TlsFree,
+            TlsGetValue => thread::TlsGetValue,
+            TlsSetValue => thread::TlsSetValue,
+            ConvertThreadToFiber => thread::ConvertThreadToFiber,
+            ConvertFiberToThread => thread::ConvertFiberToThread,
+            CreateFiber => thread::CreateFiber,
+            DeleteFiber => thread::DeleteFiber,
+            SwitchToFiber => thread::SwitchToFiber,
+            IsThreadAFiber => thread::IsThreadAFiber,
+            GetStdHandle => console::GetStdHandle,
+            AllocConsole => console::AllocConsole,
+            FreeConsole => console::FreeConsole,
+            WriteConsoleA => console::WriteConsoleA,
+            ReadConsoleA => console::ReadConsoleA,
+            ReadConsoleInputA => console::ReadConsoleInputA,
+            PeekConsoleInputA => console::PeekConsoleInputA,
+            GetNumberOfConsoleInputEvents => console::GetNumberOfConsoleInputEvents,
+            FlushConsoleInputBuffer => console::FlushConsoleInputBuffer,
+            GetConsoleMode => console::GetConsoleMode,
+            SetConsoleMode => console::SetConsoleMode,
+            CreateConsoleScreenBuffer => console::CreateConsoleScreenBuffer,
+            SetConsoleActiveScreenBuffer => console::SetConsoleActiveScreenBuffer,
+            GetConsoleScreenBufferInfo => console::GetConsoleScreenBufferInfo,
+            SetConsoleCursorInfo => console::SetConsoleCursorInfo,
+            GetConsoleCursorInfo => console::GetConsoleCursorInfo,
+            FillConsoleOutputCharacterA => console::FillConsoleOutputCharacterA,
+            FillConsoleOutputAttribute => console::FillConsoleOutputAttribute,
+            SetConsoleTitleA => console::SetConsoleTitleA,
+            GetConsoleTitleA => console::GetConsoleTitleA,
+            SetConsoleTextAttribute => console::SetConsoleTextAttribute,
+            SetConsoleCursorPosition => console::SetConsoleCursorPosition,
+        }),
+        "user32.dll" => (0x77200000, exports! {
+            MessageBoxA => user32::MessageBoxA,
+            GetDesktopWindow => user32::GetDesktopWindow,
+            FindWindowA => user32::FindWindowA,
+            GetWindowTextA => user32::GetWindowTextA,
+            SetWindowTextA => user32::SetWindowTextA,
+            ShowWindow => user32::ShowWindow,
+            UpdateWindow => user32::UpdateWindow,
+            LoadIconA => user32::LoadIconA,
+            LoadIconW => user32::LoadIconW,
+            LoadCursorA => user32::LoadCursorA,
+            LoadCursorW => user32::LoadCursorW,
+            LoadBitmapA => user32::LoadBitmapA,
+            LoadBitmapW => user32::LoadBitmapW,
+            LoadImageA => user32::LoadImageA,
+            LoadImageW => user32::LoadImageW,
+            DestroyIcon => user32::DestroyIcon,
+            DestroyCursor => user32::DestroyCursor,
+            LoadStringA => user32::LoadStringA,
+            LoadStringW => user32::LoadStringW,
+            CreateWindowExA => window::CreateWindowExA,
+            DestroyWindow => window::DestroyWindow,
+            RegisterClassA => window::RegisterClassA,
+            SendMessageA => window::SendMessageA,
+            PostMessageA => window::PostMessageA,
+            SetActiveWindow => window::SetActiveWindow,
+            SetFocus => window::SetFocus,
+            GetMessageA => message::GetMessageA,
+            PeekMessageA => message::PeekMessageA,
+            DispatchMessageA => message::DispatchMessageA,
+            TranslateMessage => message::TranslateMessage,
+            PostThreadMessageA => message::PostThreadMessageA,
+            PostQuitMessage => message::PostQuitMessage,
+            SetTimer => message::SetTimer,
+            KillTimer => message::KillTimer,
+            InvalidateRect => message::InvalidateRect,
+            ValidateRect => message::ValidateRect,
+            GetUpdateRect => message::GetUpdateRect,
+            BeginPaint => message::BeginPaint,
+            EndPaint => message::EndPaint,
+            DefWindowProcA => message::DefWindowProcA,
+        }),
+        "gdi32.dll" => (0x77300000, exports! {
+            CreateDCA => gdi::CreateDCA,
+            DeleteDC => gdi::DeleteDC,
+            GetDC => gdi::GetDC,
+            ReleaseDC => gdi::ReleaseDC,
+            CreatePen => gdi::CreatePen,
+            CreateSolidBrush => gdi::CreateSolidBrush,
+            CreateFontA => gdi::CreateFontA,
+            SelectObject => gdi::SelectObject,
+            DeleteObject => gdi::DeleteObject,
+            GetStockObject => gdi::GetStockObject,
+            SetTextColor => gdi::SetTextColor,
+            SetBkColor => gdi::SetBkColor,
+            SetBkMode => gdi::SetBkMode,
+            TextOutA => gdi::TextOutA,
+            Rectangle => gdi::Rectangle,
+            LineTo => gdi::LineTo,
+            MoveToEx => gdi::MoveToEx,
+            ChoosePixelFormat => graphics::opengl32::ChoosePixelFormat,
+            DescribePixelFormat => graphics::opengl32::DescribePixelFormat,
+            GetPixelFormat => graphics::opengl32::GetPixelFormat,
+            SetPixelFormat => graphics::opengl32::SetPixelFormat,
+            SwapBuffers => graphics::opengl32::SwapBuffers,
+        }),
+        "advapi32.dll" => (0x77400000, exports! {
+            RegOpenKeyExA => advapi32::RegOpenKeyExA,
+            RegCloseKey => advapi32::RegCloseKey,
+            RegQueryValueExA => advapi32::RegQueryValueExA,
+            RegSetValueExA => advapi32::RegSetValueExA,
+            RegCreateKeyExA => advapi32::RegCreateKeyExA,
+            RegDeleteKeyA => advapi32::RegDeleteKeyA,
+            RegDeleteValueA => advapi32::RegDeleteValueA,
+            RegEnumKeyExA => advapi32::RegEnumKeyExA,
+            RegEnumValueA => advapi32::RegEnumValueA,
+            GetFileSecurityA => advapi32::GetFileSecurityA,
+            GetFileSecurityW => advapi32::GetFileSecurityW,
+            SetFileSecurityA => advapi32::SetFileSecurityA,
+            SetFileSecurityW => advapi32::SetFileSecurityW,
+        }),
+        "dsound.dll" => (0x77500000, exports! {
+            DirectSoundCreate => dsound::DirectSoundCreate,
+            DirectSoundCreate8 => dsound::DirectSoundCreate8,
+            DirectSoundEnumerateA => dsound::DirectSoundEnumerateA,
+            DirectSoundEnumerateW => dsound::DirectSoundEnumerateW,
+        }),
+        "d3d9.dll" => (0x77600000, exports! {
+            Direct3DCreate9 => graphics::d3d9::Direct3DCreate9,
+        }),
+        "opengl32.dll" => (0x77700000, exports! {
+            wglCreateContext => graphics::opengl32::wglCreateContext,
+            wglDeleteContext => graphics::opengl32::wglDeleteContext,
+            wglMakeCurrent => graphics::opengl32::wglMakeCurrent,
+            wglGetCurrentContext => graphics::opengl32::wglGetCurrentContext,
+            wglGetCurrentDC => graphics::opengl32::wglGetCurrentDC,
+            wglGetProcAddress => graphics::opengl32::wglGetProcAddress,
+            wglShareLists => graphics::opengl32::wglShareLists,
+            wglSwapBuffers => graphics::opengl32::wglSwapBuffers,
+            glGetError => graphics::opengl32::glGetError,
+            glGetString => graphics::opengl32::glGetString,
+            glGetIntegerv => graphics::opengl32::glGetIntegerv,
+            glGetFloatv => graphics::opengl32::glGetFloatv,
+            glGetDoublev => graphics::opengl32::glGetDoublev,
+            glGetBooleanv => graphics::opengl32::glGetBooleanv,
+            glIsEnabled => graphics::opengl32::glIsEnabled,
+            glEnable => graphics::opengl32::glEnable,
+            glDisable => graphics::opengl32::glDisable,
+            glHint => graphics::opengl32::glHint,
+            glFlush => graphics::opengl32::glFlush,
+            glFinish => graphics::opengl32::glFinish,
+            glViewport => graphics::opengl32::glViewport,
+            glDepthRange => graphics::opengl32::glDepthRange,
+            glScissor => graphics::opengl32::glScissor,
+            glClear => graphics::opengl32::glClear,
+            glClearColor => graphics::opengl32::glClearColor,
+            glClearDepth => graphics::opengl32::glClearDepth,
+            glDepthFunc => graphics::opengl32::glDepthFunc,
+            glDepthMask => graphics::opengl32::glDepthMask,
+            glBlendFunc => graphics::opengl32::glBlendFunc,
+            glAlphaFunc => graphics::opengl32::glAlphaFunc,
+            glCullFace => graphics::opengl32::glCullFace,
+            glFrontFace => graphics::opengl32::glFrontFace,
+            glShadeModel => graphics::opengl32::glShadeModel,
+            glPolygonMode => graphics::opengl32::glPolygonMode,
+            glLineWidth => graphics::opengl32::glLineWidth,
+            glPointSize => graphics::opengl32::glPointSize,
+            glPixelStorei => graphics::opengl32::glPixelStorei,
+            glReadPixels => graphics::opengl32::glReadPixels,
+            glMatrixMode => graphics::opengl32::glMatrixMode,
+            glLoadIdentity => graphics::opengl32::glLoadIdentity,
+            glLoadMatrixf => graphics::opengl32::glLoadMatrixf,
+            glLoadMatrixd => graphics::opengl32::glLoadMatrixd,
+            glMultMatrixf => graphics::opengl32::glMultMatrixf,
+            glMultMatrixd => graphics::opengl32::glMultMatrixd,
+            glPushMatrix => graphics::opengl32::glPushMatrix,
+            glPopMatrix => graphics::opengl32::glPopMatrix,
+            glTranslatef => graphics::opengl32::glTranslatef,
+            glTranslated => graphics::opengl32::glTranslated,
+            glRotatef => graphics::opengl32::glRotatef,
+            glRotated => graphics::opengl32::glRotated,
+            glScalef => graphics::opengl32::glScalef,
+            glScaled => graphics::opengl32::glScaled,
+            glOrtho => graphics::opengl32::glOrtho,
+            glFrustum => graphics::opengl32::glFrustum,
+            glBegin => graphics::opengl32::glBegin,
+            glEnd => graphics::opengl32::glEnd,
+            glVertex2f => graphics::opengl32::glVertex2f,
+            glVertex2i => graphics::opengl32::glVertex2i,
+            glVertex3f => graphics::opengl32::glVertex3f,
+            glVertex4f => graphics::opengl32::glVertex4f,
+            glVertex2fv => graphics::opengl32::glVertex2fv,
+            glVertex3fv => graphics::opengl32::glVertex3fv,
+            glColor3f => graphics::opengl32::glColor3f,
+            glColor4f => graphics::opengl32::glColor4f,
+            glColor3ub => graphics::opengl32::glColor3ub,
+            glColor4ub => graphics::opengl32::glColor4ub,
+            glColor3fv => graphics::opengl32::glColor3fv,
+            glColor4fv => graphics::opengl32::glColor4fv,
+            glTexCoord2f => graphics::opengl32::glTexCoord2f,
+            glTexCoord2fv => graphics::opengl32::glTexCoord2fv,
+            glNormal3f => graphics::opengl32::glNormal3f,
+            glNormal3fv => graphics::opengl32::glNormal3fv,
+            glEnableClientState => graphics::opengl32::glEnableClientState,
+            glDisableClientState => graphics::opengl32::glDisableClientState,
+            glVertexPointer => graphics::opengl32::glVertexPointer,
+            glColorPointer => graphics::opengl32::glColorPointer,
+            glTexCoordPointer => graphics::opengl32::glTexCoordPointer,
+            glNormalPointer => graphics::opengl32::glNormalPointer,
+            glDrawArrays => graphics::opengl32::glDrawArrays,
+            glDrawElements => graphics::opengl32::glDrawElements,
+            glGenTextures => graphics::opengl32::glGenTextures,
+            glDeleteTextures => graphics::opengl32::glDeleteTextures,
+            glBindTexture => graphics::opengl32::glBindTexture,
+            glIsTexture => graphics::opengl32::glIsTexture,
+            glTexImage2D => graphics::opengl32::glTexImage2D,
+            glTexSubImage2D => graphics::opengl32::glTexSubImage2D,
+            glTexParameteri => graphics::opengl32::glTexParameteri,
+            glTexParameterf => graphics::opengl32::glTexParameterf,
+            glTexEnvi => graphics::opengl32::glTexEnvi,
+            glTexEnvf => graphics::opengl32::glTexEnvf,
+            glTexEnvfv => graphics::opengl32::glTexEnvfv,
+        }),
+        "xinput1_4.dll" => (0x77800000, exports! {
+            XInputGetState => xinput::XInputGetState,
+            XInputSetState => xinput::XInputSetState,
+            XInputGetCapabilities => xinput::XInputGetCapabilities,
+            XInputEnable => xinput::XInputEnable,
+            XInputGetBatteryInformation => xinput::XInputGetBatteryInformation,
+            XInputGetKeystroke => xinput::XInputGetKeystroke,
+        }),
+        "xinput1_3.dll" => (0x77900000, exports! {
+            XInputGetState => xinput::XInputGetState,
+            XInputSetState => xinput::XInputSetState,
+            XInputGetCapabilities => xinput::XInputGetCapabilities,
+            XInputEnable => xinput::XInputEnable,
+            XInputGetBatteryInformation => xinput::XInputGetBatteryInformation,
+            XInputGetKeystroke => xinput::XInputGetKeystroke,
+        }),
+        "xinput9_1_0.dll" => (0x77A00000, exports! {
+            XInputGetState => xinput::XInputGetState,
+            XInputSetState => xinput::XInputSetState,
+            XInputGetCapabilities => xinput::XInputGetCapabilities,
+        }),
+        "avicap32.dll" => (0x77B00000, exports! {
+            capCreateCaptureWindowA => avicap32::capCreateCaptureWindowA,
+            capCreateCaptureWindowW => avicap32::capCreateCaptureWindowW,
+            capGetDriverDescriptionA => avicap32::capGetDriverDescriptionA,
+            capGetDriverDescriptionW => avicap32::capGetDriverDescriptionW,
+        }),
+        _ => return None,
+    };
+    Some(table)
+}
+
+// None if `key` is not built in; Some(Err) if it is but there was no room
+// for its loader entry
+fn builtin_module(process_id: ProcessId, key: &str) -> Option<Result<Module, DWORD>> {
+    let (base, table) = builtin_exports(key)?;
+    let path = format!("C:\\Windows\\System32\\{}", key);
+    let Some(mut module) = Module::new(process_id, key.to_string(), path, base, 0x100000, 0) else {
+        return Some(Err(ERROR_NOT_ENOUGH_MEMORY));
+    };
+    for (ordinal, BuiltinExport(name, address)) in table.iter().enumerate() {
+        let export = Export::Address(*address as u64);
+        module.exports.by_ordinal.insert((ordinal + 1) as u16, export.clone());
+        module.exports.by_name.insert(name.to_string(), export);
+    }
+    module.ldr_entry.flags |= LDRP_ENTRY_PROCESSED;
+    Some(Ok(module))
+}
+
+// DllMain notifications run without the loader lock so entry points may
+// call back into LoadLibrary/GetProcAddress
+
+fn run_process_attach(process_id: ProcessId, loaded: &[String]) -> Result<(), DWORD> {
+    for key in loaded {
+        let entry = {
+            let mut loader = MODULE_LOADER.lock();
+            let modules = loader.process_mut(process_id)?;
+            let entry = modules.modules.get(key).map(|m| (m.dll_main(), m.handle()));
+            modules.mark_initialized(key);
+            entry
+        };
+        if let Some((Some(dll_main), handle)) = entry {
+            if dll_main(handle, DLL_PROCESS_ATTACH, core::ptr::null_mut()) == 0 {
+                return Err(ERROR_DLL_INIT_FAILED);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_process_detach(unloaded: Vec<Module>) {
+    for module in &unloaded {
+        let attached = module.ldr_entry.flags & LDRP_PROCESS_ATTACH_CALLED != 0;
+        if let (true, Some(dll_main)) = (attached, module.dll_main()) {
+            dll_main(module.handle(), DLL_PROCESS_DETACH, core::ptr::null_mut());
+        }
+    }
+    // Image memory is released when the modules drop here
+}
+
+/// Load a module into the calling process and run its initializers
+pub fn load_library(name: &str) -> Result<HANDLE, DWORD> {
+    let process_id = current_process_id();
+    let mut loaded = Vec::new();
+    let (key, handle) = {
+        let mut loader = MODULE_LOADER.lock();
+        let modules = loader.process_mut(process_id)?;
+        let key = modules.load(name, &mut loaded)?;
+        let handle = modules.modules.get(&key).map(|m| m.handle()).ok_or(ERROR_MOD_NOT_FOUND)?;
+        (key, handle)
+    };
+
+    if let Err(error) = run_process_attach(process_id, &loaded) {
+        let unloaded = MODULE_LOADER.lock().process_mut(process_id).map(|modules| modules.release(&key));
+        run_process_detach(unloaded.unwrap_or_default());
+        return Err(error);
+    }
+    Ok(handle)
+}
+
+/// Drop a reference taken by load_library
+pub fn free_library(handle: HANDLE) -> Result<(), DWORD> {
+    let process_id = current_process_id();
+    let unloaded = {
+        let mut loader = MODULE_LOADER.lock();
+        let modules = loader.process_mut(process_id)?;
+        let key = modules.module_by_handle(handle).map(|m| m.name.clone()).ok_or(ERROR_INVALID_HANDLE)?;
+        modules.release(&key)
+    };
+    run_process_detach(unloaded);
+    Ok(())
+}
+
+/// Look up an export of a loaded module, following forwarders
+pub fn get_proc_address(handle: HANDLE, target: ImportRef) -> Result<u64, DWORD> {
+    let process_id = current_process_id();
+    let mut loaded = Vec::new();
+    let address = {
+        let mut loader = MODULE_LOADER.lock();
+        let modules = loader.process_mut(process_id)?;
+        let key = modules.module_by_handle(handle).map(|m| m.name.clone()).ok_or(ERROR_INVALID_HANDLE)?;
+        modules.resolve_export(&key, target, &key, &mut loaded, 0)?
+    };
+    // Forwarders may have pulled in new modules
+    run_process_attach(process_id, &loaded)?;
+    Ok(address)
+}
+
+/// Handle of an already-loaded module, without taking a reference
+pub fn module_handle(name: &str) -> Option<HANDLE> {
+    let process_id = current_process_id();
+    let mut loader = MODULE_LOADER.lock();
+    let modules = loader.process_mut(process_id).ok()?;
+    if let Some(module) = modules.module(name) {
+        return Some(module.handle());
+    }
+    // Built-in modules are always resident
+    builtin_exports(&normalize_module_name(name))?;
+    let key = modules.load(name, &mut Vec::new()).ok()?;
+    modules.modules.get(&key).map(|m| m.handle())
+}
+
+/// Full path of a loaded module
+pub fn module_path(handle: HANDLE) -> Option<String> {
+    let process_id = current_process_id();
+    let loader = MODULE_LOADER.lock();
+    loader.process(process_id)?.module_by_handle(handle).map(|m| m.path.clone())
+}
+
+/// Where a module of the calling process is mapped, as (base, size); None
+/// for built-in modules, which have no image
+pub fn module_image(handle: HANDLE) -> Option<(u64, usize)> {
+    let process_id = current_process_id();
+    let loader = MODULE_LOADER.lock();
+    let image = loader.process(process_id)?.module_by_handle(handle)?.image.as_ref()?;
+    Some((image.base(), image.bytes().len()))
+}
+
+/// Stop DLL_THREAD_ATTACH/DETACH notifications for a module
+pub fn disable_thread_calls(handle: HANDLE) -> bool {
+    let process_id = current_process_id();
+    let mut loader = MODULE_LOADER.lock();
+    let Ok(modules) = loader.process_mut(process_id) else {
+        return false;
+    };
+    let Some(key) = modules.module_by_handle(handle).map(|m| m.name.clone()) else {
+        return false;
+    };
+    if let Some(module) = modules.modules.get_mut(&key) {
+        module.ldr_entry.flags |= LDRP_DONT_CALL_FOR_THREADS;
+    }
+    true
+}
+
+/// Deliver DLL_THREAD_ATTACH or DLL_THREAD_DETACH for the calling thread
+pub fn notify_thread(reason: DWORD) {
+    let process_id = current_process_id();
+    let targets: Vec<(DllEntryPoint, HANDLE)> = {
+        let loader = MODULE_LOADER.lock();
+        let Some(modules) = loader.process(process_id) else {
+            return;
+        };
+        let mut targets: Vec<_> = modules
+            .init_order
+            .iter()
+            .filter_map(|key| modules.modules.get(key))
+            .filter(|m| m.ldr_entry.flags & LDRP_DONT_CALL_FOR_THREADS == 0)
+            .filter_map(|m| m.dll_main().map(|entry| (entry, m.handle())))
+            .collect();
+        if reason == DLL_THREAD_DETACH {
+            targets.reverse();
+        }
+        targets
+    };
+    for (dll_main, handle) in targets {
+        dll_main(handle, reason, core::ptr::null_mut());
+    }
+}
+
+/// Detach and unmap every module of a process, most recently initialized first
+pub fn detach_process(process_id: ProcessId) {
+    let Some(mut modules) = MODULE_LOADER.lock().remove_process(process_id) else {
+        return;
+    };
+    if let Some(process) = super::thread::WIN32_THREADS.lock().process_mut(process_id) {
+        process.peb_mut().ldr = 0;
+    }
+    let mut unloaded = Vec::new();
+    for key in modules.init_order.clone().iter().rev() {
+        if let Some(module) = modules.modules.remove(key) {
+            unloaded.push(module);
+        }
+    }
+    drop(modules);
+    run_process_detach(unloaded);
+}
@@ -0,0 +1,723 @@
+// Win32 message queues and window procedure dispatch
+//
+// Every GUI thread owns a queue holding posted messages, incoming sent
+// messages from other threads, pending WM_PAINT state (as invalid regions)
+// and timers.  GetMessage/PeekMessage retrieve in the Windows priority order:
+// sent messages are dispatched first, then posted messages, then the quit
+// request, then WM_PAINT and finally WM_TIMER.
+use super::*;
+use super::window::{get_current_thread_id, Message, Point, WindowProc, WindowRect, WINDOW_MANAGER};
+use super::window::{WM_PAINT, WM_QUIT};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+pub const WM_TIMER: u32 = 0x0113;
+pub const WM_USER: u32 = 0x0400;
+
+// PeekMessage flags
+pub const PM_NOREMOVE: u32 = 0x0000;
+pub const PM_REMOVE: u32 = 0x0001;
+pub const PM_NOYIELD: u32 = 0x0002;
+
+// Posted message limit per queue, matching the Windows default
+pub const MAX_POSTED_MESSAGES: usize = 10000;
+
+// Timer callback type (TIMERPROC)
+pub type TimerProc = extern "C" fn(HANDLE, u32, usize, u32);
+
+// Timer owned by a thread queue
+#[derive(Debug, Clone)]
+pub struct WindowTimer {
+    pub hwnd: HANDLE,
+    pub id: usize,
+    pub elapse_ms: u32,
+    pub next_due: u64,
+    pub callback: Option<TimerProc>,
+}
+
+// Synchronous message sent from another thread
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub id: u64,
+    pub sender_thread: DWORD,
+    pub message: Message,
+}
+
+// RECT layout used by the C API
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RECT {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+// MSG layout used by the C API
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MSG {
+    pub hwnd: HANDLE,
+    pub message: u32,
+    pub wparam: usize,
+    pub lparam: isize,
+    pub time: u32,
+    pub pt_x: i32,
+    pub pt_y: i32,
+}
+
+// PAINTSTRUCT layout used by the C API
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PAINTSTRUCT {
+    pub hdc: HANDLE,
+    pub erase: BOOL,
+    pub paint: RECT,
+    pub restore: BOOL,
+    pub inc_update: BOOL,
+    pub reserved: [u8; 32],
+}
+
+impl From<&Message> for MSG {
+    fn from(msg: &Message) -> Self {
+        Self {
+            hwnd: msg.hwnd,
+            message: msg.message,
+            wparam: msg.wparam,
+            lparam: msg.lparam,
+            time: msg.time,
+            pt_x: msg.point.x,
+            pt_y: msg.point.y,
+        }
+    }
+}
+
+impl From<&MSG> for Message {
+    fn from(msg: &MSG) -> Self {
+        Self {
+            hwnd: msg.hwnd,
+            message: msg.message,
+            wparam: msg.wparam,
+            lparam: msg.lparam,
+            time: msg.time,
+            point: Point { x: msg.pt_x, y: msg.pt_y },
+        }
+    }
+}
+
+// Per-thread message queue
+pub struct ThreadQueue {
+    pub thread_id: DWORD,
+    posted: VecDeque<Message>,
+    sent: VecDeque<SentMessage>,
+    quit_code: Option<i32>,
+    // Invalid region of each window owned by this thread, coalesced into a
+    // single bounding rectangle so only one WM_PAINT is ever pending
+    invalid: BTreeMap<u64, (WindowRect, bool)>,
+    timers: Vec<WindowTimer>,
+}
+
+impl ThreadQueue {
+    pub fn new(thread_id: DWORD) -> Self {
+        Self {
+            thread_id,
+            posted: VecDeque::new(),
+            sent: VecDeque::new(),
+            quit_code: None,
+            invalid: BTreeMap::new(),
+            timers: Vec::new(),
+        }
+    }
+
+    fn matches(msg: &Message, hwnd: HANDLE, min: u32, max: u32) -> bool {
+        let hwnd_match = hwnd == Handle::NULL || msg.hwnd == hwnd;
+        let range_match = (min == 0 && max == 0) || (msg.message >= min && msg.message <= max);
+        hwnd_match && range_match
+    }
+
+    fn take_posted(&mut self, hwnd: HANDLE, min: u32, max: u32, remove: bool) -> Option<Message> {
+        let index = self.posted.iter().position(|m| Self::matches(m, hwnd, min, max))?;
+        if remove {
+            self.posted.remove(index)
+        } else {
+            self.posted.get(index).cloned()
+        }
+    }
+
+    fn take_paint(&mut self, hwnd: HANDLE, min: u32, max: u32, now: u64) -> Option<Message> {
+        let in_range = (min == 0 && max == 0) || (WM_PAINT >= min && WM_PAINT <= max);
+        if !in_range {
+            return None;
+        }
+        // WM_PAINT stays pending until the region is validated, so it is
+        // never removed from the queue here
+        self.invalid
+            .keys()
+            .find(|&&h| hwnd == Handle::NULL || h == hwnd.0)
+            .map(|&h| message_at(Handle(h), WM_PAINT, 0, 0, now))
+    }
+
+    fn take_timer(&mut self, hwnd: HANDLE, min: u32, max: u32, now: u64, remove: bool) -> Option<Message> {
+        let in_range = (min == 0 && max == 0) || (WM_TIMER >= min && WM_TIMER <= max);
+        if !in_range {
+            return None;
+        }
+        let timer = self.timers.iter_mut().find(|t| {
+            t.next_due <= now && (hwnd == Handle::NULL || t.hwnd == hwnd)
+        })?;
+        if remove {
+            // Timers never accumulate: a late timer fires once and is
+            // rescheduled relative to now
+            timer.next_due = now + timer.elapse_ms as u64;
+        }
+        let lparam = timer.callback.map_or(0, |cb| cb as usize as isize);
+        Some(message_at(timer.hwnd, WM_TIMER, timer.id, lparam, now))
+    }
+}
+
+// Global registry of thread queues
+pub struct MessageQueueManager {
+    queues: BTreeMap<DWORD, ThreadQueue>,
+    replies: BTreeMap<u64, isize>,
+    next_send_id: u64,
+    next_timer_id: usize,
+}
+
+impl MessageQueueManager {
+    pub fn new() -> Self {
+        Self {
+            queues: BTreeMap::new(),
+            replies: BTreeMap::new(),
+            next_send_id: 1,
+            next_timer_id: 1,
+        }
+    }
+
+    pub fn queue_mut(&mut self, thread_id: DWORD) -> &mut ThreadQueue {
+        self.queues
+            .entry(thread_id)
+            .or_insert_with(|| ThreadQueue::new(thread_id))
+    }
+
+    pub fn destroy_queue(&mut self, thread_id: DWORD) {
+        self.queues.remove(&thread_id);
+    }
+
+    pub fn post(&mut self, thread_id: DWORD, message: Message) -> bool {
+        let queue = self.queue_mut(thread_id);
+        if queue.posted.len() >= MAX_POSTED_MESSAGES {
+            return false;
+        }
+        queue.posted.push_back(message);
+        true
+    }
+
+    pub fn post_quit(&mut self, thread_id: DWORD, exit_code: i32) {
+        self.queue_mut(thread_id).quit_code = Some(exit_code);
+    }
+
+    pub fn invalidate(&mut self, thread_id: DWORD, hwnd: HANDLE, rect: WindowRect, erase: bool) {
+        let entry = self
+            .queue_mut(thread_id)
+            .invalid
+            .entry(hwnd.0)
+            .or_insert((WindowRect::new(0, 0, 0, 0), false));
+        entry.0 = entry.0.union(&rect);
+        entry.1 |= erase;
+    }
+
+    pub fn validate(&mut self, thread_id: DWORD, hwnd: HANDLE) -> Option<(WindowRect, bool)> {
+        self.queues.get_mut(&thread_id)?.invalid.remove(&hwnd.0)
+    }
+
+    pub fn update_rect(&self, thread_id: DWORD, hwnd: HANDLE) -> Option<WindowRect> {
+        self.queues.get(&thread_id)?.invalid.get(&hwnd.0).map(|(r, _)| *r)
+    }
+
+    pub fn set_timer(
+        &mut self,
+        thread_id: DWORD,
+        hwnd: HANDLE,
+        id: usize,
+        elapse_ms: u32,
+        callback: Option<TimerProc>,
+        now: u64,
+    ) -> usize {
+        // Thread timers (no window) get a system-assigned identifier
+        let id = if hwnd == Handle::NULL {
+            let id = self.next_timer_id;
+            self.next_timer_id += 1;
+            id
+        } else {
+            id
+        };
+        let elapse_ms = elapse_ms.max(USER_TIMER_MINIMUM);
+        let queue = self.queue_mut(thread_id);
+
+        // Re-arming an existing timer replaces it
+        queue.timers.retain(|t| !(t.hwnd == hwnd && t.id == id));
+        queue.timers.push(WindowTimer {
+            hwnd,
+            id,
+            elapse_ms,
+            next_due: now + elapse_ms as u64,
+            callback,
+        });
+        id
+    }
+
+    pub fn kill_timer(&mut self, thread_id: DWORD, hwnd: HANDLE, id: usize) -> bool {
+        let Some(queue) = self.queues.get_mut(&thread_id) else {
+            return false;
+        };
+        let before = queue.timers.len();
+        queue.timers.retain(|t| !(t.hwnd == hwnd && t.id == id));
+        queue.timers.len() != before
+    }
+
+    /// The TIMERPROC registered through SetTimer for a timer, if any
+    pub fn timer_proc(&self, thread_id: DWORD, hwnd: HANDLE, id: usize) -> Option<TimerProc> {
+        self.queues
+            .get(&thread_id)?
+            .timers
+            .iter()
+            .find(|t| t.hwnd == hwnd && t.id == id)?
+            .callback
+    }
+
+    /// Drop all queued state that refers to a destroyed window
+    pub fn purge_window(&mut self, hwnd: HANDLE) {
+        for queue in self.queues.values_mut() {
+            queue.posted.retain(|m| m.hwnd != hwnd);
+            queue.invalid.remove(&hwnd.0);
+            queue.timers.retain(|t| t.hwnd != hwnd);
+        }
+    }
+
+    fn enqueue_send(&mut self, sender: DWORD, receiver: DWORD, message: Message) -> u64 {
+        let id = self.next_send_id;
+        self.next_send_id += 1;
+        self.queue_mut(receiver).sent.push_back(SentMessage {
+            id,
+            sender_thread: sender,
+            message,
+        });
+        id
+    }
+
+    fn next_sent(&mut self, thread_id: DWORD) -> Option<SentMessage> {
+        self.queues.get_mut(&thread_id)?.sent.pop_front()
+    }
+
+    /// Retrieve the next message for a thread following Windows priority order
+    fn retrieve(
+        &mut self,
+        thread_id: DWORD,
+        hwnd: HANDLE,
+        min: u32,
+        max: u32,
+        remove: bool,
+        now: u64,
+    ) -> Option<Message> {
+        let queue = self.queue_mut(thread_id);
+
+        if let Some(msg) = queue.take_posted(hwnd, min, max, remove) {
+            return Some(msg);
+        }
+
+        let quit_in_range = (min == 0 && max == 0) || (WM_QUIT >= min && WM_QUIT <= max);
+        if quit_in_range {
+            if let Some(code) = queue.quit_code {
+                if remove {
+                    queue.quit_code = None;
+                }
+                return Some(message_at(Handle::NULL, WM_QUIT, code as usize, 0, now));
+            }
+        }
+
+        if let Some(msg) = queue.take_paint(hwnd, min, max, now) {
+            return Some(msg);
+        }
+
+        queue.take_timer(hwnd, min, max, now, remove)
+    }
+}
+
+pub const USER_TIMER_MINIMUM: u32 = 0x0000000A;
+
+lazy_static! {
+    pub static ref MESSAGE_QUEUES: Mutex<MessageQueueManager> = Mutex::new(MessageQueueManager::new());
+}
+
+fn tick_count() -> u64 {
+    crate::timer::TIMER.lock().get_uptime_ms()
+}
+
+fn message_at(hwnd: HANDLE, message: u32, wparam: usize, lparam: isize, now: u64) -> Message {
+    Message {
+        hwnd,
+        message,
+        wparam,
+        lparam,
+        time: now as u32,
+        point: Point { x: 0, y: 0 },
+    }
+}
+
+fn make_message(hwnd: HANDLE, message: u32, wparam: usize, lparam: isize) -> Message {
+    message_at(hwnd, message, wparam, lparam, tick_count())
+}
+
+/// Thread that owns a window, or None if the handle is not a window
+fn window_thread(hwnd: HANDLE) -> Option<DWORD> {
+    WINDOW_MANAGER.lock().window_thread(hwnd)
+}
+
+/// Invoke a window procedure without holding any window manager locks, so the
+/// procedure is free to call back into USER32
+fn call_window_proc(hwnd: HANDLE, msg: u32, wparam: usize, lparam: isize) -> isize {
+    let wnd_proc: Option<WindowProc> = WINDOW_MANAGER.lock().window_proc(hwnd);
+    match wnd_proc {
+        Some(proc_fn) => proc_fn(hwnd, msg, wparam, lparam),
+        None => 0,
+    }
+}
+
+/// Dispatch every pending cross-thread send addressed to `thread_id`
+fn dispatch_sent_messages(thread_id: DWORD) {
+    loop {
+        let next = MESSAGE_QUEUES.lock().next_sent(thread_id);
+        let Some(sent) = next else { break };
+        let m = &sent.message;
+        let result = call_window_proc(m.hwnd, m.message, m.wparam, m.lparam);
+        MESSAGE_QUEUES.lock().replies.insert(sent.id, result);
+    }
+}
+
+/// Send a message and wait for the window procedure's result. Sends to a
+/// window owned by the calling thread are dispatched directly; sends to other
+/// threads are queued and the caller keeps servicing its own incoming sends
+/// while it waits, which prevents two threads sending to each other from
+/// deadlocking.
+pub fn send_message(hwnd: HANDLE, msg: u32, wparam: usize, lparam: isize) -> isize {
+    let Some(target) = window_thread(hwnd) else {
+        return 0;
+    };
+    let current = get_current_thread_id();
+
+    if target == current {
+        return call_window_proc(hwnd, msg, wparam, lparam);
+    }
+
+    let message = make_message(hwnd, msg, wparam, lparam);
+    let id = MESSAGE_QUEUES.lock().enqueue_send(current, target, message);
+
+    loop {
+        {
+            let mut queues = MESSAGE_QUEUES.lock();
+            if let Some(result) = queues.replies.remove(&id) {
+                return result;
+            }
+            // Receiver exited without replying
+            if !queues.queues.contains_key(&target) {
+                return 0;
+            }
+        }
+        dispatch_sent_messages(current);
+        crate::smp::yield_cpu();
+    }
+}
+
+/// Post a message to the queue of the thread owning `hwnd`. A NULL window
+/// posts a thread message to the caller's own queue.
+pub fn post_message(hwnd: HANDLE, msg: u32, wparam: usize, lparam: isize) -> bool {
+    let thread_id = if hwnd == Handle::NULL {
+        get_current_thread_id()
+    } else {
+        match window_thread(hwnd) {
+            Some(t) => t,
+            None => return false,
+        }
+    };
+    let message = make_message(hwnd, msg, wparam, lparam);
+    MESSAGE_QUEUES.lock().post(thread_id, message)
+}
+
+/// Post a message to a thread's queue with no window attached
+pub fn post_thread_message(thread_id: DWORD, msg: u32, wparam: usize, lparam: isize) -> bool {
+    let message = make_message(Handle::NULL, msg, wparam, lparam);
+    MESSAGE_QUEUES.lock().post(thread_id, message)
+}
+
+/// Retrieve a message without blocking
+pub fn peek_message(hwnd: HANDLE, min: u32, max: u32, flags: u32) -> Option<Message> {
+    let thread_id = get_current_thread_id();
+    dispatch_sent_messages(thread_id);
+
+    let now = tick_count();
+    MESSAGE_QUEUES
+        .lock()
+        .retrieve(thread_id, hwnd, min, max, flags & PM_REMOVE != 0, now)
+}
+
+/// Block until a message is available
+pub fn get_message(hwnd: HANDLE, min: u32, max: u32) -> Message {
+    loop {
+        if let Some(msg) = peek_message(hwnd, min, max, PM_REMOVE) {
+            return msg;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Deliver a retrieved message to its window procedure
+pub fn dispatch_message(msg: &Message) -> isize {
+    if msg.message == WM_TIMER && msg.lparam != 0 {
+        // lparam names the TIMERPROC for callback timers, but anyone can
+        // post a WM_TIMER: only a proc registered for the timer is called
+        let thread_id = if msg.hwnd == Handle::NULL {
+            Some(get_current_thread_id())
+        } else {
+            window_thread(msg.hwnd)
+        };
+        let callback = thread_id
+            .and_then(|thread_id| MESSAGE_QUEUES.lock().timer_proc(thread_id, msg.hwnd, msg.wparam))
+            .filter(|&callback| callback as usize as isize == msg.lparam);
+        if let Some(callback) = callback {
+            callback(msg.hwnd, WM_TIMER, msg.wparam, tick_count() as u32);
+            return 0;
+        }
+    }
+
+    if msg.hwnd == Handle::NULL {
+        return 0;
+    }
+
+    let result = call_window_proc(msg.hwnd, msg.message, msg.wparam, msg.lparam);
+
+    // A window procedure that ignores WM_PAINT must not leave it pending
+    // forever, so validate the region the way DefWindowProc would
+    if msg.message == WM_PAINT {
+        if let Some(thread_id) = window_thread(msg.hwnd) {
+            MESSAGE_QUEUES.lock().validate(thread_id, msg.hwnd);
+        }
+    }
+    result
+}
+
+/// Add a rectangle (or the whole client area) to a window's update region
+pub fn invalidate_rect(hwnd: HANDLE, rect: Option<WindowRect>, erase: bool) -> bool {
+    let (thread_id, client) = {
+        let manager = WINDOW_MANAGER.lock();
+        match (manager.window_thread(hwnd), manager.client_rect(hwnd)) {
+            (Some(t), Some(c)) => (t, c),
+            _ => return false,
+        }
+    };
+    let rect = rect.unwrap_or(client);
+    if rect.is_empty() {
+        return true;
+    }
+    MESSAGE_QUEUES.lock().invalidate(thread_id, hwnd, rect, erase);
+    true
+}
+
+/// Remove a window's update region
+pub fn validate_rect(hwnd: HANDLE) -> bool {
+    match window_thread(hwnd) {
+        Some(thread_id) => {
+            MESSAGE_QUEUES.lock().validate(thread_id, hwnd);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Dispatch WM_PAINT immediately if the window has a pending update region
+pub fn update_window(hwnd: HANDLE) -> bool {
+    let Some(thread_id) = window_thread(hwnd) else {
+        return false;
+    };
+    let pending = MESSAGE_QUEUES.lock().update_rect(thread_id, hwnd).is_some();
+    if pending {
+        send_message(hwnd, WM_PAINT, 0, 0);
+        MESSAGE_QUEUES.lock().validate(thread_id, hwnd);
+    }
+    true
+}
+
+fn rect_to_c(rect: &WindowRect) -> RECT {
+    RECT { left: rect.left, top: rect.top, right: rect.right, bottom: rect.bottom }
+}
+
+fn rect_from_c(rect: &RECT) -> WindowRect {
+    WindowRect::new(rect.left, rect.top, rect.right, rect.bottom)
+}
+
+// Message API Functions
+
+/// GetMessageA - Retrieve a message, blocking until one is available
+#[no_mangle]
+pub extern "C" fn GetMessageA(msg: *mut MSG, hwnd: HANDLE, min: u32, max: u32) -> BOOL {
+    if msg.is_null() {
+        return -1;
+    }
+    let message = get_message(hwnd, min, max);
+    let quit = message.message == WM_QUIT;
+    unsafe { *msg = MSG::from(&message); }
+    if quit { 0 } else { 1 }
+}
+
+/// PeekMessageA - Check the message queue without blocking
+#[no_mangle]
+pub extern "C" fn PeekMessageA(msg: *mut MSG, hwnd: HANDLE, min: u32, max: u32, remove: u32) -> BOOL {
+    match peek_message(hwnd, min, max, remove) {
+        Some(message) => {
+            if !msg.is_null() {
+                unsafe { *msg = MSG::from(&message); }
+            }
+            1
+        }
+        None => 0,
+    }
+}
+
+/// DispatchMessageA - Send a retrieved message to its window procedure
+#[no_mangle]
+pub extern "C" fn DispatchMessageA(msg: *const MSG) -> isize {
+    if msg.is_null() {
+        return 0;
+    }
+    let message = Message::from(unsafe { &*msg });
+    dispatch_message(&message)
+}
+
+/// TranslateMessage - Generate character messages from key messages
+#[no_mangle]
+pub extern "C" fn TranslateMessage(_msg: *const MSG) -> BOOL {
+    // Keyboard input already arrives as WM_CHAR from the input layer
+    0
+}
+
+/// PostThreadMessageA - Post a message to a thread's queue
+#[no_mangle]
+pub extern "C" fn PostThreadMessageA(thread_id: DWORD, msg: u32, wparam: usize, lparam: isize) -> BOOL {
+    post_thread_message(thread_id, msg, wparam, lparam) as BOOL
+}
+
+/// PostQuitMessage - Ask the calling thread's message loop to terminate
+#[no_mangle]
+pub extern "C" fn PostQuitMessage(exit_code: i32) {
+    MESSAGE_QUEUES.lock().post_quit(get_current_thread_id(), exit_code);
+}
+
+/// SetTimer - Create a timer that posts WM_TIMER or calls a TIMERPROC
+#[no_mangle]
+pub extern "C" fn SetTimer(hwnd: HANDLE, id: usize, elapse: u32, callback: Option<TimerProc>) -> usize {
+    let thread_id = if hwnd == Handle::NULL {
+        get_current_thread_id()
+    } else {
+        match window_thread(hwnd) {
+            Some(t) => t,
+            None => return 0,
+        }
+    };
+    let now = tick_count();
+    MESSAGE_QUEUES.lock().set_timer(thread_id, hwnd, id, elapse, callback, now)
+}
+
+/// KillTimer - Destroy a timer
+#[no_mangle]
+pub extern "C" fn KillTimer(hwnd: HANDLE, id: usize) -> BOOL {
+    let thread_id = if hwnd == Handle::NULL {
+        get_current_thread_id()
+    } else {
+        match window_thread(hwnd) {
+            Some(t) => t,
+            None => return 0,
+        }
+    };
+    MESSAGE_QUEUES.lock().kill_timer(thread_id, hwnd, id) as BOOL
+}
+
+/// InvalidateRect - Add a rectangle to a window's update region
+#[no_mangle]
+pub extern "C" fn InvalidateRect(hwnd: HANDLE, rect: *const RECT, erase: BOOL) -> BOOL {
+    let rect = if rect.is_null() {
+        None
+    } else {
+        Some(rect_from_c(unsafe { &*rect }))
+    };
+    invalidate_rect(hwnd, rect, erase != 0) as BOOL
+}
+
+/// ValidateRect - Remove a window's update region
+#[no_mangle]
+pub extern "C" fn ValidateRect(hwnd: HANDLE, _rect: *const RECT) -> BOOL {
+    validate_rect(hwnd) as BOOL
+}
+
+/// GetUpdateRect - Get the bounding rectangle of the update region
+#[no_mangle]
+pub extern "C" fn GetUpdateRect(hwnd: HANDLE, rect: *mut RECT, _erase: BOOL) -> BOOL {
+    let Some(thread_id) = window_thread(hwnd) else {
+        return 0;
+    };
+    let update = MESSAGE_QUEUES.lock().update_rect(thread_id, hwnd);
+    if !rect.is_null() {
+        let value = update.map(|r| rect_to_c(&r)).unwrap_or_default();
+        unsafe { *rect = value; }
+    }
+    update.is_some() as BOOL
+}
+
+/// BeginPaint - Prepare a window for painting and validate its update region
+#[no_mangle]
+pub extern "C" fn BeginPaint(hwnd: HANDLE, paint: *mut PAINTSTRUCT) -> HANDLE {
+    let Some(thread_id) = window_thread(hwnd) else {
+        return Handle::NULL;
+    };
+    let (rect, erase) = MESSAGE_QUEUES
+        .lock()
+        .validate(thread_id, hwnd)
+        .unwrap_or((WindowRect::new(0, 0, 0, 0), false));
+
+    if !paint.is_null() {
+        unsafe {
+            *paint = PAINTSTRUCT {
+                hdc: hwnd,
+                erase: erase as BOOL,
+                paint: rect_to_c(&rect),
+                restore: 0,
+                inc_update: 0,
+                reserved: [0; 32],
+            };
+        }
+    }
+    // The window handle doubles as its device context
+    hwnd
+}
+
+/// EndPaint - Mark the end of painting
+#[no_mangle]
+pub extern "C" fn EndPaint(_hwnd: HANDLE, _paint: *const PAINTSTRUCT) -> BOOL {
+    1
+}
+
+/// DefWindowProcA - Default message processing
+#[no_mangle]
+pub extern "C" fn DefWindowProcA(hwnd: HANDLE, msg: u32, _wparam: usize, _lparam: isize) -> isize {
+    match msg {
+        WM_PAINT => {
+            validate_rect(hwnd);
+            0
+        }
+        super::window::WM_CLOSE => {
+            super::window::DestroyWindow(hwnd);
+            0
+        }
+        _ => 0,
+    }
+}
@@ -4,12 +4,20 @@ pub mod gdi;
 pub mod advapi32;
 pub mod syscall;
 pub mod window;
+pub mod message;
+pub mod thread;
+pub mod loader;
 pub mod console;
 pub mod winmm;
 pub mod winsock;
 pub mod printing;
 pub mod ole32;
 pub mod graphics;
+pub mod clipboard;
+pub mod resource;
+pub mod dsound;
+pub mod xinput;
+pub mod avicap32;
 
 
 // Windows-style handles
@@ -27,6 +35,7 @@ pub const ERROR_FILE_NOT_FOUND: u32 = 2;
 pub const ERROR_ACCESS_DENIED: u32 = 5;
 pub const ERROR_INVALID_HANDLE: u32 = 6;
 pub const ERROR_NOT_ENOUGH_MEMORY: u32 = 8;
+pub const ERROR_LOCK_VIOLATION: u32 = 33;
 
 // Windows types
 pub type DWORD = u32;
@@ -47,6 +56,14 @@ pub struct ProcessInformation {
     pub thread_id: DWORD,
 }
 
+// Security attributes structure
+#[repr(C)]
+pub struct SecurityAttributes {
+    pub length: DWORD,
+    pub security_descriptor: *mut u8,
+    pub inherit_handle: BOOL,
+}
+
 // Startup info structure
 #[repr(C)]
 pub struct StartupInfo {
@@ -93,4 +110,62 @@ impl Default for StartupInfo {
             std_error: Handle::NULL,
         }
     }
-}
\ No newline at end of file
+}
+// SYSTEMTIME
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTime {
+    pub year: u16,
+    pub month: u16,
+    pub day_of_week: u16,
+    pub day: u16,
+    pub hour: u16,
+    pub minute: u16,
+    pub second: u16,
+    pub milliseconds: u16,
+}
+
+impl From<crate::time::DateTime> for SystemTime {
+    fn from(time: crate::time::DateTime) -> Self {
+        Self {
+            year: time.year,
+            month: time.month as u16,
+            day_of_week: time.weekday() as u16,
+            day: time.day as u16,
+            hour: time.hour as u16,
+            minute: time.minute as u16,
+            second: time.second as u16,
+            milliseconds: time.millisecond,
+        }
+    }
+}
+
+impl SystemTime {
+    // Out-of-range fields are caught by DateTime::is_valid; day_of_week is ignored
+    pub fn to_date_time(&self) -> crate::time::DateTime {
+        let field = |value: u16| value.min(u8::MAX as u16) as u8;
+        crate::time::DateTime {
+            year: self.year,
+            month: field(self.month),
+            day: field(self.day),
+            hour: field(self.hour),
+            minute: field(self.minute),
+            second: field(self.second),
+            millisecond: self.milliseconds,
+        }
+    }
+}
+
+// FILETIME: 100 ns intervals since 1601-01-01
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileTime {
+    pub low_date_time: DWORD,
+    pub high_date_time: DWORD,
+}
+
+impl From<u64> for FileTime {
+    fn from(value: u64) -> Self {
+        Self { low_date_time: value as DWORD, high_date_time: (value >> 32) as DWORD }
+    }
+}
@@ -5,6 +5,9 @@ use crate::nt::NtStatus;
 use alloc::vec::Vec;
 use alloc::string::{String, ToString};
 use alloc::format;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 
 // Print API Constants
 pub const PRINTER_ENUM_DEFAULT: u32 = 0x00000001;
@@ -152,6 +155,21 @@ pub const ERROR_PRINTER_DELETED: u32 = 1907;
 pub const ERROR_INVALID_PRINTER_NAME_2: u32 = 1908;
 pub const ERROR_PRINTER_ALREADY_EXISTS_2: u32 = 1909;
 
+// An open printer handle: the printer it names and the document being
+// written through it
+struct OpenPrinterHandle {
+    printer_name: String,
+    job_id: Option<u32>,
+}
+
+static PRINTER_HANDLES: Mutex<BTreeMap<u64, OpenPrinterHandle>> = Mutex::new(BTreeMap::new());
+static NEXT_PRINTER_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+// The document being written through a handle
+fn handle_job(printer_handle: HANDLE) -> Option<u32> {
+    PRINTER_HANDLES.lock().get(&printer_handle.0).and_then(|handle| handle.job_id)
+}
+
 // Helper function to convert NtStatus to Win32 error
 fn nt_status_to_print_error(status: NtStatus) -> u32 {
     match status {
@@ -204,9 +222,12 @@ pub extern "C" fn OpenPrinter(
         
         // Check if printer exists
         if let Some(_printer_info) = print_get_printer_info(&name) {
-            // Create a handle (use pointer address as handle ID)
-            let handle = Handle(printer_name as u64);
-            *printer_handle = handle;
+            let handle = NEXT_PRINTER_HANDLE.fetch_add(1, Ordering::Relaxed);
+            PRINTER_HANDLES.lock().insert(handle, OpenPrinterHandle {
+                printer_name: name.to_string(),
+                job_id: None,
+            });
+            *printer_handle = Handle(handle);
             
             crate::println!("Print: Opened printer '{}'", name);
             1 // TRUE
@@ -219,7 +240,7 @@ pub extern "C" fn OpenPrinter(
 
 /// Close a printer handle
 pub extern "C" fn ClosePrinter(printer_handle: HANDLE) -> BOOL {
-    if printer_handle == Handle::NULL {
+    if PRINTER_HANDLES.lock().remove(&printer_handle.0).is_none() {
         unsafe { crate::win32::kernel32::SetLastError(ERROR_INVALID_HANDLE); }
         return 0; // FALSE
     }
@@ -266,10 +287,13 @@ pub extern "C" fn StartDocPrinter(
             doc_name = String::from_utf8_lossy(&name_vec).to_string();
         }
         
-        // Use default printer for this handle (simplified)
-        if let Some(default_printer) = print_get_default_printer() {
-            match print_start_doc(&default_printer, &doc_name) {
+        let printer_name = PRINTER_HANDLES.lock().get(&printer_handle.0).map(|handle| handle.printer_name.clone());
+        if let Some(printer_name) = printer_name {
+            match print_start_doc(&printer_name, &doc_name) {
                 Ok(job_id) => {
+                    if let Some(handle) = PRINTER_HANDLES.lock().get_mut(&printer_handle.0) {
+                        handle.job_id = Some(job_id);
+                    }
                     crate::println!("Print: Started document '{}' (Job ID: {})", doc_name, job_id);
                     job_id
                 }
@@ -279,7 +303,7 @@ pub extern "C" fn StartDocPrinter(
                 }
             }
         } else {
-            crate::win32::kernel32::SetLastError(ERROR_INVALID_PRINTER_NAME);
+            crate::win32::kernel32::SetLastError(ERROR_INVALID_HANDLE);
             0
         }
     }
@@ -300,8 +324,10 @@ pub extern "C" fn WritePrinter(
     unsafe {
         let data = core::slice::from_raw_parts(buffer, count as usize);
         
-        // Use handle value as job ID (simplified)
-        let job_id = (printer_handle.0 & 0xFFFFFFFF) as u32;
+        let Some(job_id) = handle_job(printer_handle) else {
+            crate::win32::kernel32::SetLastError(ERROR_INVALID_HANDLE);
+            return 0; // FALSE
+        };
         
         match print_write_data(job_id, data) {
             Ok(bytes_written) => {
@@ -326,11 +352,16 @@ pub extern "C" fn EndDocPrinter(printer_handle: HANDLE) -> BOOL {
         return 0; // FALSE
     }
 
-    // Use handle value as job ID (simplified)
-    let job_id = (printer_handle.0 & 0xFFFFFFFF) as u32;
+    let Some(job_id) = handle_job(printer_handle) else {
+        unsafe { crate::win32::kernel32::SetLastError(ERROR_INVALID_HANDLE); }
+        return 0; // FALSE
+    };
     
     match print_end_doc(job_id) {
         NtStatus::Success => {
+            if let Some(handle) = PRINTER_HANDLES.lock().get_mut(&printer_handle.0) {
+                handle.job_id = None;
+            }
             crate::println!("Print: Ended document for job {}", job_id);
             1 // TRUE
         }
@@ -0,0 +1,607 @@
+//! Resources of PE images
+//!
+//! An image's resource directory is a tree three levels deep: type, name,
+//! language. Entries at each level are named by a number or by a counted
+//! UTF-16 string, and the leaves give the RVA and size of the data. Images
+//! are read mapped, as the loader holds them, or straight from their
+//! files, for the shell's file icons, with RVAs turned into file offsets
+//! through the section table.
+//!
+//! Icons and cursors come in groups: an RT_GROUP_ICON lists the sizes an
+//! icon is drawn at, each an RT_ICON of its own, and the size asked for
+//! picks one. Each is a DIB whose height counts the AND mask after the
+//! colours; a 32-bit one carries alpha, and the rest are transparent where
+//! the mask is set. Sizes stored as PNG are passed over for the others.
+//! ICO and CUR files hold the same images under a directory of their own.
+//!
+//! Strings are kept sixteen to an RT_STRING block, block n holding IDs
+//! 16(n-1) to 16n-1, each counted in UTF-16 units and not NUL-terminated.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt;
+
+use super::{Handle, DWORD, HANDLE};
+use crate::clipboard::{self, Image};
+use crate::fs::unicode::{self, UpCase};
+
+pub const RT_CURSOR: u16 = 1;
+pub const RT_BITMAP: u16 = 2;
+pub const RT_ICON: u16 = 3;
+pub const RT_STRING: u16 = 6;
+pub const RT_GROUP_CURSOR: u16 = 12;
+pub const RT_GROUP_ICON: u16 = 14;
+pub const RT_VERSION: u16 = 16;
+
+pub const ERROR_RESOURCE_DATA_NOT_FOUND: DWORD = 1812;
+pub const ERROR_RESOURCE_TYPE_NOT_FOUND: DWORD = 1813;
+pub const ERROR_RESOURCE_NAME_NOT_FOUND: DWORD = 1814;
+pub const ERROR_RESOURCE_LANG_NOT_FOUND: DWORD = 1815;
+
+const IMAGE_DOS_SIGNATURE: u16 = 0x5A4D;
+const IMAGE_NT_SIGNATURE: u32 = 0x0000_4550;
+const IMAGE_NT_OPTIONAL_HDR32_MAGIC: u16 = 0x10B;
+const IMAGE_NT_OPTIONAL_HDR64_MAGIC: u16 = 0x20B;
+const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+
+// High bits of a directory entry: the name is a string, the entry leads
+// to another directory
+const NAME_IS_STRING: u32 = 0x8000_0000;
+const DATA_IS_DIRECTORY: u32 = 0x8000_0000;
+
+// Languages tried when the caller's is not there
+const LANG_NEUTRAL: u16 = 0x0000;
+const LANG_EN_US: u16 = 0x0409;
+
+const STRINGS_PER_BLOCK: u16 = 16;
+
+const VS_FIXEDFILEINFO_SIGNATURE: u32 = 0xFEEF_04BD;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1A\n";
+const BITMAPFILEHEADER_SIZE: usize = 14;
+
+const PAGE_SIZE: u64 = 4096;
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// A resource's type or name: a number, or a string compared without case
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResourceName {
+    Id(u16),
+    Name(String),
+}
+
+impl ResourceName {
+    /// A name as text; "#123" is the number 123
+    pub fn parse(text: &str) -> Self {
+        match text.strip_prefix('#').and_then(|digits| digits.parse().ok()) {
+            Some(id) => ResourceName::Id(id),
+            None => ResourceName::Name(String::from(text)),
+        }
+    }
+
+    /// A name as an A call takes it: a number below 0x10000, as
+    /// MAKEINTRESOURCE makes, or a NUL-terminated string
+    ///
+    /// # Safety
+    /// Above 0x10000, `name` must point at a NUL-terminated string
+    pub unsafe fn from_ansi(name: *const u8) -> Option<Self> {
+        if (name as usize) < 0x1_0000 {
+            return (!name.is_null()).then_some(ResourceName::Id(name as u16));
+        }
+        let text = core::ffi::CStr::from_ptr(name as *const i8).to_str().ok()?;
+        Some(Self::parse(text))
+    }
+
+    /// A name as a W call takes it
+    ///
+    /// # Safety
+    /// Above 0x10000, `name` must point at a NUL-terminated string
+    pub unsafe fn from_wide(name: *const u16) -> Option<Self> {
+        if (name as usize) < 0x1_0000 {
+            return (!name.is_null()).then_some(ResourceName::Id(name as u16));
+        }
+        super::advapi32::wide_to_string(name).map(|text| Self::parse(&text))
+    }
+
+    fn matches(&self, other: &ResourceName) -> bool {
+        match (self, other) {
+            (ResourceName::Id(a), ResourceName::Id(b)) => a == b,
+            (ResourceName::Name(a), ResourceName::Name(b)) => UpCase::standard().eq_ignore_case(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceName::Id(id) => write!(f, "#{}", id),
+            ResourceName::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A type's name as resource scripts give it, such as ICON or VERSION
+pub fn type_name(kind: &ResourceName) -> String {
+    let name = match kind {
+        ResourceName::Id(1) => "CURSOR",
+        ResourceName::Id(2) => "BITMAP",
+        ResourceName::Id(3) => "ICON",
+        ResourceName::Id(4) => "MENU",
+        ResourceName::Id(5) => "DIALOG",
+        ResourceName::Id(6) => "STRING",
+        ResourceName::Id(7) => "FONTDIR",
+        ResourceName::Id(8) => "FONT",
+        ResourceName::Id(9) => "ACCELERATOR",
+        ResourceName::Id(10) => "RCDATA",
+        ResourceName::Id(11) => "MESSAGETABLE",
+        ResourceName::Id(12) => "GROUP_CURSOR",
+        ResourceName::Id(14) => "GROUP_ICON",
+        ResourceName::Id(16) => "VERSION",
+        ResourceName::Id(24) => "MANIFEST",
+        _ => return format!("{}", kind),
+    };
+    String::from(name)
+}
+
+/// A resource found in an image
+pub struct Resource<'a> {
+    pub data: &'a [u8],
+    /// Where its IMAGE_RESOURCE_DATA_ENTRY is in the image, which
+    /// FindResource points at
+    pub entry: usize,
+    pub language: u16,
+}
+
+/// An icon or cursor image, its pixels transparent where alpha is 0
+pub struct Icon {
+    pub image: Image,
+    pub hotspot: (u16, u16),
+}
+
+/// What RT_VERSION says of a file
+pub struct VersionInfo {
+    pub file_version: [u16; 4],
+    pub product_version: [u16; 4],
+    /// The first table of StringFileInfo, such as ("FileDescription", ...)
+    pub strings: Vec<(String, String)>,
+}
+
+/// An image's resources
+pub struct PeImage<'a> {
+    data: &'a [u8],
+    // (RVA, size, file offset) of each section, for an image read as a file
+    sections: Option<Vec<(u32, u32, u32)>>,
+    // Where the resource directory starts in `data`
+    root: usize,
+}
+
+impl<'a> PeImage<'a> {
+    /// An image as the loader maps it
+    pub fn mapped(data: &'a [u8]) -> Option<Self> {
+        Self::new(data, false)
+    }
+
+    /// An image as its file holds it
+    pub fn file(data: &'a [u8]) -> Option<Self> {
+        Self::new(data, true)
+    }
+
+    fn new(data: &'a [u8], file: bool) -> Option<Self> {
+        if u16_at(data, 0)? != IMAGE_DOS_SIGNATURE {
+            return None;
+        }
+        let nt = u32_at(data, 0x3C)? as usize;
+        if u32_at(data, nt)? != IMAGE_NT_SIGNATURE {
+            return None;
+        }
+        let section_count = u16_at(data, nt + 6)? as usize;
+        let optional_size = u16_at(data, nt + 20)? as usize;
+        let optional = nt + 24;
+        let directories = match u16_at(data, optional)? {
+            IMAGE_NT_OPTIONAL_HDR32_MAGIC => optional + 96,
+            IMAGE_NT_OPTIONAL_HDR64_MAGIC => optional + 112,
+            _ => return None,
+        };
+        if u32_at(data, directories - 4)? as usize <= IMAGE_DIRECTORY_ENTRY_RESOURCE {
+            return None;
+        }
+        let root = u32_at(data, directories + IMAGE_DIRECTORY_ENTRY_RESOURCE * 8)?;
+        if root == 0 {
+            return None;
+        }
+
+        let mut image = PeImage { data, sections: None, root: 0 };
+        if file {
+            let table = optional + optional_size;
+            let section = |i: usize| {
+                let at = table + i * 40;
+                Some((u32_at(data, at + 12)?, u32_at(data, at + 16)?, u32_at(data, at + 20)?))
+            };
+            image.sections = Some((0..section_count).map(section).collect::<Option<_>>()?);
+        }
+        image.root = image.offset(root, 16)?;
+        Some(image)
+    }
+
+    /// Where the image starts in memory
+    pub fn base(&self) -> u64 {
+        self.data.as_ptr() as u64
+    }
+
+    // Where `len` bytes at `rva` are in `data`
+    fn offset(&self, rva: u32, len: usize) -> Option<usize> {
+        let at = match &self.sections {
+            None => rva as usize,
+            Some(sections) => {
+                let &(start, size, raw) = sections.iter().find(|&&(start, size, _)| rva.wrapping_sub(start) < size)?;
+                (raw + (rva - start)) as usize
+            }
+        };
+        (at.checked_add(len)? <= self.data.len()).then_some(at)
+    }
+
+    // The entries of the directory at `directory` from the root, as (name,
+    // where what it leads to is from the root, whether that is a directory)
+    fn entries(&self, directory: usize) -> Vec<(ResourceName, usize, bool)> {
+        let (data, at) = (self.data, self.root + directory);
+        let (Some(named), Some(numbered)) = (u16_at(data, at + 12), u16_at(data, at + 14)) else {
+            return Vec::new();
+        };
+        let read = |i: usize| {
+            let entry = at + 16 + i * 8;
+            let (name, target) = (u32_at(data, entry)?, u32_at(data, entry + 4)?);
+            let name = if name & NAME_IS_STRING != 0 {
+                let string = self.root + (name & !NAME_IS_STRING) as usize;
+                let len = u16_at(data, string)? as usize;
+                ResourceName::Name(unicode::decode_utf16le(data.get(string + 2..string + 2 + len * 2)?))
+            } else {
+                ResourceName::Id(name as u16)
+            };
+            Some((name, (target & !DATA_IS_DIRECTORY) as usize, target & DATA_IS_DIRECTORY != 0))
+        };
+        (0..named as usize + numbered as usize).map_while(read).collect()
+    }
+
+    // The data of the IMAGE_RESOURCE_DATA_ENTRY at `entry` in the image
+    fn data_at(&self, entry: usize) -> Option<&'a [u8]> {
+        let (rva, size) = (u32_at(self.data, entry)?, u32_at(self.data, entry + 4)? as usize);
+        let at = self.offset(rva, size)?;
+        self.data.get(at..at + size)
+    }
+
+    /// The resource of type `kind` called `name`, in `language` when it is
+    /// given and there, else language neutral, else US English, else the
+    /// first language it comes in
+    pub fn find(&self, kind: &ResourceName, name: &ResourceName, language: Option<u16>) -> Result<Resource<'a>, DWORD> {
+        let types = self.entries(0);
+        let (_, names, _) = types
+            .iter()
+            .find(|(found, _, directory)| *directory && found.matches(kind))
+            .ok_or(ERROR_RESOURCE_TYPE_NOT_FOUND)?;
+        let names = self.entries(*names);
+        let (_, languages, _) = names
+            .iter()
+            .find(|(found, _, directory)| *directory && found.matches(name))
+            .ok_or(ERROR_RESOURCE_NAME_NOT_FOUND)?;
+
+        let languages = self.entries(*languages);
+        let leaf = |language: u16| {
+            languages.iter().find(|(found, _, directory)| !directory && *found == ResourceName::Id(language))
+        };
+        let chosen = language.into_iter().chain([LANG_NEUTRAL, LANG_EN_US]).find_map(leaf);
+        let (found, entry, _) = chosen
+            .or_else(|| languages.iter().find(|(_, _, directory)| !directory))
+            .ok_or(ERROR_RESOURCE_LANG_NOT_FOUND)?;
+        let language = match found {
+            ResourceName::Id(language) => *language,
+            ResourceName::Name(_) => LANG_NEUTRAL,
+        };
+        let entry = self.root + entry;
+        let data = self.data_at(entry).ok_or(ERROR_RESOURCE_DATA_NOT_FOUND)?;
+        Ok(Resource { data, entry, language })
+    }
+
+    /// The data of the resource FindResource found at `entry`
+    pub fn resource_at(&self, entry: usize) -> Option<&'a [u8]> {
+        (entry >= self.root).then(|| self.data_at(entry)).flatten()
+    }
+
+    /// The names of the resources of type `kind`, in the directory's order
+    pub fn names(&self, kind: u16) -> Vec<ResourceName> {
+        let types = self.entries(0);
+        let Some((_, names, true)) = types.iter().find(|(found, _, _)| *found == ResourceName::Id(kind)) else {
+            return Vec::new();
+        };
+        self.entries(*names).into_iter().filter(|(_, _, directory)| *directory).map(|(name, _, _)| name).collect()
+    }
+
+    /// Every resource, as (type, name, language, size)
+    pub fn list(&self) -> Vec<(ResourceName, ResourceName, u16, usize)> {
+        let mut list = Vec::new();
+        for (kind, names, _) in self.entries(0).into_iter().filter(|(_, _, directory)| *directory) {
+            for (name, languages, _) in self.entries(names).into_iter().filter(|(_, _, directory)| *directory) {
+                for (language, entry, directory) in self.entries(languages) {
+                    let Some(data) = (!directory).then(|| self.data_at(self.root + entry)).flatten() else {
+                        continue;
+                    };
+                    let language = if let ResourceName::Id(language) = language { language } else { LANG_NEUTRAL };
+                    list.push((kind.clone(), name.clone(), language, data.len()));
+                }
+            }
+        }
+        list
+    }
+
+    /// String `id` as it is stored, in little-endian UTF-16; None if it is
+    /// missing or empty, as LoadString takes an empty one
+    pub fn string_data(&self, id: u16, language: Option<u16>) -> Option<&'a [u8]> {
+        let block_id = ResourceName::Id(id / STRINGS_PER_BLOCK + 1);
+        let block = self.find(&ResourceName::Id(RT_STRING), &block_id, language).ok()?.data;
+        let mut at = 0;
+        for _ in 0..id % STRINGS_PER_BLOCK {
+            at += 2 + u16_at(block, at)? as usize * 2;
+        }
+        let len = u16_at(block, at)? as usize;
+        if len == 0 {
+            return None;
+        }
+        block.get(at + 2..at + 2 + len * 2)
+    }
+
+    pub fn string(&self, id: u16, language: Option<u16>) -> Option<String> {
+        self.string_data(id, language).map(unicode::decode_utf16le)
+    }
+
+    /// Icon group `name`, or with `cursor` cursor group `name`, at the size
+    /// nearest `size` pixels
+    pub fn icon(&self, name: &ResourceName, cursor: bool, size: u32) -> Result<Icon, DWORD> {
+        let (group_kind, kind) = if cursor { (RT_GROUP_CURSOR, RT_CURSOR) } else { (RT_GROUP_ICON, RT_ICON) };
+        let group = self.find(&ResourceName::Id(group_kind), name, None)?;
+        let data = group.data;
+        // A GRPICONDIR, then 14-byte entries ending in each image's ID;
+        // icons give their width in a byte, cursors in a word
+        let count = u16_at(data, 4).ok_or(ERROR_RESOURCE_DATA_NOT_FOUND)? as usize;
+        let entry = |i: usize| {
+            let at = 6 + i * 14;
+            let width = if cursor { u16_at(data, at)? as u32 } else { *data.get(at)? as u32 };
+            Some((if width == 0 { 256 } else { width }, u16_at(data, at + 6)?, u16_at(data, at + 12)?))
+        };
+        let mut entries: Vec<_> = (0..count).map_while(entry).collect();
+        by_fit(&mut entries, size);
+
+        for (_, _, id) in entries {
+            let Ok(resource) = self.find(&ResourceName::Id(kind), &ResourceName::Id(id), Some(group.language)) else {
+                continue;
+            };
+            // A cursor's image starts with its hotspot
+            let (hotspot, dib) = if cursor {
+                match (u16_at(resource.data, 0), u16_at(resource.data, 2)) {
+                    (Some(x), Some(y)) => ((x, y), &resource.data[4..]),
+                    _ => continue,
+                }
+            } else {
+                ((0, 0), resource.data)
+            };
+            if let Some(image) = decode_icon_image(dib) {
+                return Ok(Icon { image, hotspot });
+            }
+        }
+        Err(ERROR_RESOURCE_DATA_NOT_FOUND)
+    }
+
+    /// Bitmap `name`
+    pub fn bitmap(&self, name: &ResourceName) -> Result<Image, DWORD> {
+        let resource = self.find(&ResourceName::Id(RT_BITMAP), name, None)?;
+        clipboard::decode_dib(resource.data).ok_or(ERROR_RESOURCE_DATA_NOT_FOUND)
+    }
+
+    pub fn version(&self) -> Option<VersionInfo> {
+        let resource = self.find(&ResourceName::Id(RT_VERSION), &ResourceName::Id(1), None).ok()?;
+        let (root, _) = Block::parse(resource.data)?;
+        let fixed = root.value;
+        if u32_at(fixed, 0)? != VS_FIXEDFILEINFO_SIGNATURE {
+            return None;
+        }
+        let version = |at: usize| -> Option<[u16; 4]> {
+            let (high, low) = (u32_at(fixed, at)?, u32_at(fixed, at + 4)?);
+            Some([(high >> 16) as u16, high as u16, (low >> 16) as u16, low as u16])
+        };
+
+        let mut strings = Vec::new();
+        let string_file_info = root.children().find(|child| child.key == "StringFileInfo");
+        if let Some(table) = string_file_info.and_then(|info| info.children().next()) {
+            for string in table.children() {
+                let value = unicode::decode_utf16le(string.value);
+                strings.push((string.key, String::from(value.trim_end_matches('\0'))));
+            }
+        }
+        Some(VersionInfo { file_version: version(8)?, product_version: version(16)?, strings })
+    }
+}
+
+// Order icon directory entries, as (width, bits a pixel, where), best
+// first: nearest in size, larger before smaller, then deepest
+fn by_fit<T>(entries: &mut [(u32, u16, T)], size: u32) {
+    entries.sort_by_key(|&(width, bits, _)| (width.abs_diff(size), width < size, Reverse(bits)));
+}
+
+// A node of a VS_VERSIONINFO: a key, a value and children, each starting
+// on a 32-bit boundary
+struct Block<'a> {
+    key: String,
+    value: &'a [u8],
+    children: &'a [u8],
+}
+
+impl<'a> Block<'a> {
+    // The block at the start of `data`, and what follows it
+    fn parse(data: &'a [u8]) -> Option<(Block<'a>, &'a [u8])> {
+        let align = |at: usize| (at + 3) & !3;
+        let length = u16_at(data, 0)? as usize;
+        let value_length = u16_at(data, 2)? as usize;
+        // Text values are counted in UTF-16 units
+        let value_length = if u16_at(data, 4)? == 1 { value_length * 2 } else { value_length };
+        let block = data.get(..length)?;
+
+        let key = block.get(6..)?.chunks_exact(2).take_while(|unit| *unit != [0, 0]).count();
+        let value_at = align(6 + key * 2 + 2).min(length);
+        let value = block.get(value_at..value_at + value_length).unwrap_or(&block[value_at..]);
+        let children = &block[align(value_at + value_length).min(length)..];
+        let key = unicode::decode_utf16le(&block[6..6 + key * 2]);
+        Some((Block { key, value, children }, data.get(align(length)..).unwrap_or(&[])))
+    }
+
+    fn children(&self) -> impl Iterator<Item = Block<'a>> {
+        let mut rest = self.children;
+        core::iter::from_fn(move || {
+            let (block, after) = Block::parse(rest)?;
+            rest = after;
+            Some(block)
+        })
+    }
+}
+
+/// An icon or cursor image as RT_ICON and ICO files hold it: a DIB of
+/// twice its height, the colours and then the AND mask, both bottom-up
+pub fn decode_icon_image(dib: &[u8]) -> Option<Image> {
+    if dib.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let header = u32_at(dib, 0)? as usize;
+    let width = u32_at(dib, 4)? as usize;
+    let height = (u32_at(dib, 8)? as i32).unsigned_abs() as usize / 2;
+    let bits = u16_at(dib, 14)? as usize;
+    let colors = u32_at(dib, 32)? as usize;
+
+    let mut colour = dib.to_vec();
+    colour[8..12].copy_from_slice(&(height as i32).to_le_bytes());
+    let mut image = clipboard::decode_dib(&colour)?;
+    if image.width != width || image.height != height {
+        return None;
+    }
+
+    let palette = match bits {
+        1 | 4 | 8 if colors == 0 => 1 << bits,
+        1 | 4 | 8 => colors.min(1 << bits),
+        _ => 0,
+    };
+    let pixels_at = header + palette * 4;
+    let stride = (width * bits).div_ceil(32) * 4;
+    let mask_at = pixels_at + stride * height;
+    let mask_stride = width.div_ceil(32) * 4;
+    // Icons from before alpha have 32-bit pixels with alpha left at 0
+    let alpha = dib.get(pixels_at..mask_at).filter(|data| bits == 32 && data.chunks_exact(4).any(|p| p[3] != 0));
+
+    for y in 0..height {
+        let row = height - 1 - y;
+        for x in 0..width {
+            let opacity = match alpha {
+                Some(data) => data[row * stride + x * 4 + 3],
+                None => match dib.get(mask_at + row * mask_stride + x / 8) {
+                    Some(mask) if mask & (0x80 >> (x % 8)) != 0 => 0,
+                    _ => 0xFF,
+                },
+            };
+            let pixel = &mut image.pixels[y * width + x];
+            *pixel = (*pixel & 0x00FF_FFFF) | ((opacity as u32) << 24);
+        }
+    }
+    Some(image)
+}
+
+/// The image of an ICO or CUR file nearest `size` pixels
+pub fn decode_ico(data: &[u8], size: u32) -> Option<Icon> {
+    let kind = u16_at(data, 2)?;
+    if u16_at(data, 0)? != 0 || !matches!(kind, 1 | 2) {
+        return None;
+    }
+    // 16-byte entries; where an icon has its planes and bits a pixel, a
+    // cursor has its hotspot
+    let entry = |i: usize| {
+        let at = 6 + i * 16;
+        let width = match *data.get(at)? {
+            0 => 256,
+            width => width as u32,
+        };
+        let (planes_or_x, bits_or_y) = (u16_at(data, at + 4)?, u16_at(data, at + 6)?);
+        let (len, offset) = (u32_at(data, at + 8)? as usize, u32_at(data, at + 12)? as usize);
+        let (bits, hotspot) = if kind == 1 { (bits_or_y, (0, 0)) } else { (0, (planes_or_x, bits_or_y)) };
+        Some((width, bits, (offset, len, hotspot)))
+    };
+    let mut entries: Vec<_> = (0..u16_at(data, 4)? as usize).map_while(entry).collect();
+    by_fit(&mut entries, size);
+    entries.into_iter().find_map(|(_, _, (offset, len, hotspot))| {
+        let image = decode_icon_image(data.get(offset..offset.checked_add(len)?)?)?;
+        Some(Icon { image, hotspot })
+    })
+}
+
+/// A BMP file: a BITMAPFILEHEADER, then the DIB
+pub fn decode_bmp(data: &[u8]) -> Option<Image> {
+    if !data.starts_with(b"BM") {
+        return None;
+    }
+    clipboard::decode_dib(data.get(BITMAPFILEHEADER_SIZE..)?)
+}
+
+/// The icon the shell shows for a file: an ICO or CUR file's image, or the
+/// first icon group of a program or DLL, nearest `size` pixels
+pub fn file_icon(path: &str, size: u32) -> Option<Icon> {
+    let (_, extension) = path.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    if !matches!(extension.as_str(), "ico" | "cur" | "exe" | "dll" | "cpl" | "scr") {
+        return None;
+    }
+    let data = crate::fs::vfs::VFS.lock().read_file(path).ok()?;
+    if matches!(extension.as_str(), "ico" | "cur") {
+        return decode_ico(&data, size);
+    }
+    let image = PeImage::file(&data)?;
+    let name = image.names(RT_GROUP_ICON).into_iter().next()?;
+    image.icon(&name, false, size).ok()
+}
+
+/// The resources of a module of the calling process, NULL being the
+/// executable. Built-in modules have none.
+pub fn module_image(module: HANDLE) -> Result<PeImage<'static>, DWORD> {
+    let (base, size) = match super::loader::module_image(module) {
+        Some(image) => image,
+        None => executable_image(module).ok_or(ERROR_RESOURCE_DATA_NOT_FOUND)?,
+    };
+    // The image stays mapped until the module is unloaded, which is as
+    // long as Windows keeps a resource's memory
+    let data = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    PeImage::mapped(data).ok_or(ERROR_RESOURCE_DATA_NOT_FOUND)
+}
+
+// The executable's image, at the PEB's ImageBaseAddress, when it is
+// mapped there
+fn executable_image(module: HANDLE) -> Option<(u64, usize)> {
+    use crate::memory::paging::translate_current;
+
+    let process_id = crate::process::PROCESS_MANAGER.lock().current_process.unwrap_or(crate::process::ProcessId(1));
+    let base = super::thread::WIN32_THREADS.lock().process_mut(process_id).map_or(0, |process| process.peb().image_base_address);
+    if base == 0 || (module != Handle::NULL && module.0 != base) {
+        return None;
+    }
+    let mapped = |address: u64| x86_64::VirtAddr::try_new(address).is_ok_and(|page| translate_current(page).is_some());
+    if !mapped(base) {
+        return None;
+    }
+    let headers = unsafe { core::slice::from_raw_parts(base as *const u8, PAGE_SIZE as usize) };
+    if u16_at(headers, 0)? != IMAGE_DOS_SIGNATURE {
+        return None;
+    }
+    // SizeOfImage is at the same place in both optional headers
+    let size = u32_at(headers, u32_at(headers, 0x3C)? as usize + 24 + 56)? as u64;
+    (base..base + size).step_by(PAGE_SIZE as usize).all(mapped).then_some((base, size as usize))
+}
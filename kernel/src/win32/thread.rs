@@ -0,0 +1,1141 @@
+// Win32 thread layer: TEB/PEB, thread-local storage, priorities and fibers
+//
+// Each Win32 thread is backed by a kernel thread from the process thread
+// manager.  The TEB and PEB follow the x64 Windows layout so code compiled for
+// Windows can address them through GS (gs:[0x30] is the TEB self pointer,
+// gs:[0x60] the PEB).  Both are mapped into the process's user address space,
+// as are a thread's TLS expansion slots, so the kernel reaches them only while
+// that process is the current one.  The scheduler calls `activate_thread` on
+// every switch to point the user GS base at the incoming thread's TEB.
+use super::*;
+use crate::process::{ProcessId, ThreadId};
+use crate::process::thread::{InitialRegisters, ThreadState, THREAD_MANAGER};
+use crate::memory::mmap::{self, Backing};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem::{offset_of, size_of};
+use x86_64::VirtAddr;
+use crate::sync::Mutex;
+use lazy_static::lazy_static;
+
+pub const TLS_MINIMUM_AVAILABLE: usize = 64;
+pub const TLS_EXPANSION_SLOTS: usize = 1024;
+pub const TLS_OUT_OF_INDEXES: DWORD = 0xFFFFFFFF;
+
+pub const CREATE_SUSPENDED: DWORD = 0x00000004;
+pub const STILL_ACTIVE: DWORD = 0x00000103;
+pub const MAXIMUM_SUSPEND_COUNT: DWORD = 0x7F;
+
+// Thread priorities relative to the process priority class
+pub const THREAD_PRIORITY_IDLE: i32 = -15;
+pub const THREAD_PRIORITY_LOWEST: i32 = -2;
+pub const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+pub const THREAD_PRIORITY_NORMAL: i32 = 0;
+pub const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+pub const THREAD_PRIORITY_HIGHEST: i32 = 2;
+pub const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+pub const THREAD_PRIORITY_ERROR_RETURN: i32 = 0x7FFFFFFF;
+
+// Process priority classes
+pub const IDLE_PRIORITY_CLASS: DWORD = 0x00000040;
+pub const BELOW_NORMAL_PRIORITY_CLASS: DWORD = 0x00004000;
+pub const NORMAL_PRIORITY_CLASS: DWORD = 0x00000020;
+pub const ABOVE_NORMAL_PRIORITY_CLASS: DWORD = 0x00008000;
+pub const HIGH_PRIORITY_CLASS: DWORD = 0x00000080;
+pub const REALTIME_PRIORITY_CLASS: DWORD = 0x00000100;
+
+// Pseudo handle returned by GetCurrentThread
+pub const CURRENT_THREAD_HANDLE: Handle = Handle(0xFFFFFFFFFFFFFFFE);
+
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+const FIBER_STACK_SIZE: usize = 64 * 1024;
+
+pub type ThreadStartRoutine = extern "C" fn(*mut c_void) -> DWORD;
+pub type FiberStartRoutine = extern "C" fn(*mut c_void);
+
+// NT_TIB - first member of the TEB
+#[repr(C)]
+#[derive(Debug)]
+pub struct NtTib {
+    pub exception_list: u64,
+    pub stack_base: u64,
+    pub stack_limit: u64,
+    pub sub_system_tib: u64,
+    pub fiber_data: u64,
+    pub arbitrary_user_pointer: u64,
+    pub self_ptr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ClientId {
+    pub unique_process: u64,
+    pub unique_thread: u64,
+}
+
+// Thread Environment Block (x64 layout)
+#[repr(C)]
+pub struct Teb {
+    pub nt_tib: NtTib,                              // 0x0000
+    pub environment_pointer: u64,                   // 0x0038
+    pub client_id: ClientId,                        // 0x0040
+    pub active_rpc_handle: u64,                     // 0x0050
+    pub thread_local_storage_pointer: u64,          // 0x0058
+    pub process_environment_block: u64,             // 0x0060
+    pub last_error_value: u32,                      // 0x0068
+    pub count_of_owned_critical_sections: u32,      // 0x006C
+    _reserved1: [u8; 0x1480 - 0x70],
+    pub tls_slots: [u64; TLS_MINIMUM_AVAILABLE],    // 0x1480
+    pub tls_links: [u64; 2],                        // 0x1680
+    _reserved2: [u8; 0x1780 - 0x1690],
+    pub tls_expansion_slots: u64,                   // 0x1780
+}
+
+// Process Environment Block (x64 layout)
+#[repr(C)]
+pub struct Peb {
+    pub inherited_address_space: u8,                // 0x0000
+    pub read_image_file_exec_options: u8,           // 0x0001
+    pub being_debugged: u8,                         // 0x0002
+    pub bit_field: u8,                              // 0x0003
+    _padding0: [u8; 4],
+    pub mutant: u64,                                // 0x0008
+    pub image_base_address: u64,                    // 0x0010
+    pub ldr: u64,                                   // 0x0018
+    pub process_parameters: u64,                    // 0x0020
+    pub sub_system_data: u64,                       // 0x0028
+    pub process_heap: u64,                          // 0x0030
+    pub fast_peb_lock: u64,                         // 0x0038
+    _reserved1: [u8; 0x78 - 0x40],
+    pub tls_bitmap: u64,                            // 0x0078
+    pub tls_bitmap_bits: [u32; 2],                  // 0x0080
+    _reserved2: [u8; 0xB8 - 0x88],
+    pub number_of_processors: u32,                  // 0x00B8
+    pub nt_global_flag: u32,                        // 0x00BC
+    _reserved3: [u8; 0x118 - 0xC0],
+    pub os_major_version: u32,                      // 0x0118
+    pub os_minor_version: u32,                      // 0x011C
+    pub os_build_number: u16,                       // 0x0120
+    pub os_csd_version: u16,                        // 0x0122
+    pub os_platform_id: u32,                        // 0x0124
+}
+
+// Guard the canonical offsets that compiled Windows code relies on
+const _: () = {
+    assert!(offset_of!(Teb, nt_tib) + offset_of!(NtTib, self_ptr) == 0x30);
+    assert!(offset_of!(Teb, client_id) == 0x40);
+    assert!(offset_of!(Teb, thread_local_storage_pointer) == 0x58);
+    assert!(offset_of!(Teb, process_environment_block) == 0x60);
+    assert!(offset_of!(Teb, last_error_value) == 0x68);
+    assert!(offset_of!(Teb, tls_slots) == 0x1480);
+    assert!(offset_of!(Teb, tls_expansion_slots) == 0x1780);
+    assert!(offset_of!(Peb, being_debugged) == 0x02);
+    assert!(offset_of!(Peb, image_base_address) == 0x10);
+    assert!(offset_of!(Peb, ldr) == 0x18);
+    assert!(offset_of!(Peb, process_heap) == 0x30);
+    assert!(offset_of!(Peb, tls_bitmap_bits) == 0x80);
+    assert!(offset_of!(Peb, number_of_processors) == 0xB8);
+    assert!(offset_of!(Peb, os_major_version) == 0x118);
+};
+
+/// Map `size` zeroed bytes, readable and writable, into the user address
+/// space of `process_id`, which must be the current process. None if it has
+/// no user address space or no room.
+fn map_user_block(process_id: ProcessId, size: usize) -> Option<u64> {
+    let prot = mmap::PROT_READ | mmap::PROT_WRITE;
+    let flags = mmap::MAP_PRIVATE | mmap::MAP_ANONYMOUS;
+    mmap::map(process_id.0, VirtAddr::new(0), size as u64, prot, flags, Backing::Anonymous)
+        .ok()
+        .map(|start| start.as_u64())
+}
+
+// A thread or fiber stack in the process's user address space, unmapped
+// when dropped
+pub struct UserStack {
+    process_id: ProcessId,
+    pub base: u64,
+    pub limit: u64,
+}
+
+impl UserStack {
+    fn map(process_id: ProcessId, size: usize) -> Option<Self> {
+        let limit = map_user_block(process_id, size)?;
+        Some(Self { process_id, base: limit + size as u64, limit })
+    }
+}
+
+impl Drop for UserStack {
+    fn drop(&mut self) {
+        let _ = mmap::unmap(self.process_id.0, VirtAddr::new(self.limit), self.base - self.limit);
+    }
+}
+
+impl Teb {
+    /// Map a TEB into the process and return its user address
+    fn map(process_id: ProcessId, thread_id: ThreadId, peb: u64, stack_base: u64, stack_limit: u64) -> Option<u64> {
+        let address = map_user_block(process_id, size_of::<Teb>())?;
+        // Anonymous pages come zeroed; fill in the rest
+        let teb = unsafe { &mut *(address as *mut Teb) };
+        teb.nt_tib.stack_base = stack_base;
+        teb.nt_tib.stack_limit = stack_limit;
+        teb.nt_tib.self_ptr = address;
+        teb.client_id = ClientId {
+            unique_process: process_id.0 as u64,
+            unique_thread: thread_id.0 as u64,
+        };
+        teb.process_environment_block = peb;
+        Some(address)
+    }
+}
+
+impl Peb {
+    /// Map a PEB into the process and return its user address
+    fn map(process_id: ProcessId, image_base: u64) -> Option<u64> {
+        let address = map_user_block(process_id, size_of::<Peb>())?;
+        let peb = unsafe { &mut *(address as *mut Peb) };
+        peb.image_base_address = image_base;
+        peb.number_of_processors = crate::smp::SMP_MANAGER.lock().cpu_count().max(1) as u32;
+        peb.os_major_version = 5;
+        peb.os_minor_version = 2;
+        peb.os_build_number = 3790;
+        peb.os_platform_id = 2; // VER_PLATFORM_WIN32_NT
+        Some(address)
+    }
+}
+
+// Callee-saved register state of a fiber
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FiberContext {
+    pub rsp: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+pub struct Fiber {
+    pub context: FiberContext,
+    // Stored as an address so the manager stays Send
+    pub parameter: u64,
+    pub stack: Option<UserStack>,
+    pub stack_base: u64,
+    pub stack_limit: u64,
+    // Thread currently running the fiber; a fiber runs on one thread at a time
+    pub running_on: Option<ThreadId>,
+}
+
+// Win32 view of a kernel thread
+pub struct Win32Thread {
+    pub thread_id: ThreadId,
+    pub process_id: ProcessId,
+    pub handle: HANDLE,
+    /// User address of the TEB, which also points at the TLS expansion
+    /// slots once a thread has any
+    pub teb: u64,
+    pub stack: Option<UserStack>,
+    pub relative_priority: i32,
+    pub suspend_count: DWORD,
+    pub exit_code: Option<DWORD>,
+    pub current_fiber: Option<u64>,
+}
+
+// Per-process Win32 state
+pub struct Win32Process {
+    pub process_id: ProcessId,
+    /// User address of the PEB
+    pub peb: u64,
+    pub priority_class: DWORD,
+    pub tls_in_use: Vec<bool>,
+}
+
+pub struct Win32ThreadManager {
+    threads: BTreeMap<u32, Win32Thread>,
+    processes: BTreeMap<u32, Win32Process>,
+    handles: BTreeMap<u64, ThreadId>,
+    fibers: BTreeMap<u64, Box<Fiber>>,
+    next_handle: u64,
+}
+
+lazy_static! {
+    pub static ref WIN32_THREADS: Mutex<Win32ThreadManager> = Mutex::new(Win32ThreadManager::new());
+}
+
+impl Win32ThreadManager {
+    pub fn new() -> Self {
+        Self {
+            threads: BTreeMap::new(),
+            processes: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            fibers: BTreeMap::new(),
+            next_handle: 0x30000,
+        }
+    }
+
+    fn allocate_handle(&mut self) -> HANDLE {
+        let handle = Handle(self.next_handle);
+        self.next_handle += 4;
+        handle
+    }
+
+    /// Win32 state for a process, created on first use. None if its PEB
+    /// cannot be mapped, as for a process without a user address space.
+    pub fn process_mut(&mut self, process_id: ProcessId) -> Option<&mut Win32Process> {
+        if !self.processes.contains_key(&process_id.0) {
+            let peb = Peb::map(process_id, 0x400000)?;
+            self.processes.insert(process_id.0, Win32Process {
+                process_id,
+                peb,
+                priority_class: NORMAL_PRIORITY_CLASS,
+                tls_in_use: vec![false; TLS_MINIMUM_AVAILABLE + TLS_EXPANSION_SLOTS],
+            });
+        }
+        self.processes.get_mut(&process_id.0)
+    }
+
+    pub fn peb_address(&mut self, process_id: ProcessId) -> Option<u64> {
+        self.process_mut(process_id).map(|process| process.peb)
+    }
+
+    /// Attach Win32 state (TEB, handle) to an existing kernel thread of the
+    /// current process. None if its TEB cannot be mapped.
+    pub fn register_thread(
+        &mut self,
+        thread_id: ThreadId,
+        process_id: ProcessId,
+        stack: Option<UserStack>,
+    ) -> Option<HANDLE> {
+        if let Some(existing) = self.threads.get(&thread_id.0) {
+            return Some(existing.handle);
+        }
+
+        let peb = self.peb_address(process_id)?;
+        let (stack_base, stack_limit) = stack.as_ref().map_or((0, 0), |s| (s.base, s.limit));
+        let teb = Teb::map(process_id, thread_id, peb, stack_base, stack_limit)?;
+        let handle = self.allocate_handle();
+
+        self.threads.insert(thread_id.0, Win32Thread {
+            thread_id,
+            process_id,
+            handle,
+            teb,
+            stack,
+            relative_priority: THREAD_PRIORITY_NORMAL,
+            suspend_count: 0,
+            exit_code: None,
+            current_fiber: None,
+        });
+        self.handles.insert(handle.0, thread_id);
+        Some(handle)
+    }
+
+    pub fn thread(&self, thread_id: ThreadId) -> Option<&Win32Thread> {
+        self.threads.get(&thread_id.0)
+    }
+
+    pub fn thread_mut(&mut self, thread_id: ThreadId) -> Option<&mut Win32Thread> {
+        self.threads.get_mut(&thread_id.0)
+    }
+
+    pub fn resolve_handle(&self, handle: HANDLE) -> Option<ThreadId> {
+        if handle == CURRENT_THREAD_HANDLE {
+            return Some(current_thread_id());
+        }
+        self.handles.get(&handle.0).copied()
+    }
+
+    pub fn tls_alloc(&mut self, process_id: ProcessId) -> Option<usize> {
+        let process = self.process_mut(process_id)?;
+        let index = process.tls_in_use.iter().position(|used| !used)?;
+        process.tls_in_use[index] = true;
+        if index < TLS_MINIMUM_AVAILABLE {
+            let word = index / 32;
+            process.peb_mut().tls_bitmap_bits[word] |= 1 << (index % 32);
+        }
+
+        // New slots start out zero in every thread of the process
+        for thread in self.threads.values_mut().filter(|t| t.process_id == process_id) {
+            thread.set_tls(index, 0);
+        }
+        Some(index)
+    }
+
+    pub fn tls_free(&mut self, process_id: ProcessId, index: usize) -> bool {
+        let Some(process) = self.process_mut(process_id) else {
+            return false;
+        };
+        if index >= process.tls_in_use.len() || !process.tls_in_use[index] {
+            return false;
+        }
+        process.tls_in_use[index] = false;
+        if index < TLS_MINIMUM_AVAILABLE {
+            let word = index / 32;
+            process.peb_mut().tls_bitmap_bits[word] &= !(1 << (index % 32));
+        }
+        for thread in self.threads.values_mut().filter(|t| t.process_id == process_id) {
+            thread.set_tls(index, 0);
+        }
+        true
+    }
+
+    pub fn tls_in_use(&mut self, process_id: ProcessId, index: usize) -> bool {
+        self.process_mut(process_id)
+            .and_then(|process| process.tls_in_use.get(index).copied())
+            .unwrap_or(false)
+    }
+
+    /// NT base priority (0-31) for a thread, combining its process class
+    pub fn base_priority(&mut self, thread_id: ThreadId) -> Option<u8> {
+        let (process_id, relative) = {
+            let thread = self.threads.get(&thread_id.0)?;
+            (thread.process_id, thread.relative_priority)
+        };
+        let class = self.process_mut(process_id)?.priority_class;
+        Some(compute_base_priority(class, relative))
+    }
+
+    pub fn fiber(&mut self, address: u64) -> Option<&mut Fiber> {
+        self.fibers.get_mut(&address).map(|f| &mut **f)
+    }
+}
+
+impl Win32Process {
+    // Only while the process is the current one
+    pub fn peb(&self) -> &Peb {
+        unsafe { &*(self.peb as *const Peb) }
+    }
+
+    pub fn peb_mut(&mut self) -> &mut Peb {
+        unsafe { &mut *(self.peb as *mut Peb) }
+    }
+}
+
+impl Win32Thread {
+    // Only while the thread's process is the current one
+    pub fn teb(&self) -> &Teb {
+        unsafe { &*(self.teb as *const Teb) }
+    }
+
+    pub fn teb_mut(&mut self) -> &mut Teb {
+        unsafe { &mut *(self.teb as *mut Teb) }
+    }
+
+    // The TLS expansion slots, once mapped
+    fn tls_expansion(&mut self) -> Option<&mut [u64; TLS_EXPANSION_SLOTS]> {
+        let slots = self.teb().tls_expansion_slots;
+        (slots != 0).then(|| unsafe { &mut *(slots as *mut [u64; TLS_EXPANSION_SLOTS]) })
+    }
+
+    pub fn get_tls(&mut self, index: usize) -> u64 {
+        if index < TLS_MINIMUM_AVAILABLE {
+            self.teb().tls_slots[index]
+        } else {
+            self.tls_expansion()
+                .and_then(|slots| slots.get(index - TLS_MINIMUM_AVAILABLE).copied())
+                .unwrap_or(0)
+        }
+    }
+
+    /// False if the expansion slots were needed and could not be mapped
+    pub fn set_tls(&mut self, index: usize, value: u64) -> bool {
+        if index < TLS_MINIMUM_AVAILABLE {
+            self.teb_mut().tls_slots[index] = value;
+            return true;
+        }
+        if self.teb().tls_expansion_slots == 0 {
+            if value == 0 {
+                return true;
+            }
+            let Some(slots) = map_user_block(self.process_id, TLS_EXPANSION_SLOTS * size_of::<u64>()) else {
+                return false;
+            };
+            self.teb_mut().tls_expansion_slots = slots;
+        }
+        if let Some(slot) = self.tls_expansion().and_then(|slots| slots.get_mut(index - TLS_MINIMUM_AVAILABLE)) {
+            *slot = value;
+        }
+        true
+    }
+}
+
+/// Map a priority class and relative thread priority to an NT priority level
+pub fn compute_base_priority(priority_class: DWORD, relative: i32) -> u8 {
+    let class_base: i32 = match priority_class {
+        IDLE_PRIORITY_CLASS => 4,
+        BELOW_NORMAL_PRIORITY_CLASS => 6,
+        ABOVE_NORMAL_PRIORITY_CLASS => 10,
+        HIGH_PRIORITY_CLASS => 13,
+        REALTIME_PRIORITY_CLASS => 24,
+        _ => 8,
+    };
+    let realtime = priority_class == REALTIME_PRIORITY_CLASS;
+
+    // Saturating values pin to the ends of the class's range
+    let priority = match relative {
+        THREAD_PRIORITY_IDLE => if realtime { 16 } else { 1 },
+        THREAD_PRIORITY_TIME_CRITICAL => if realtime { 31 } else { 15 },
+        r => class_base + r,
+    };
+    if realtime {
+        priority.clamp(16, 31) as u8
+    } else {
+        priority.clamp(1, 15) as u8
+    }
+}
+
+/// Scheduler priority for an NT priority level. Levels 16-31 are the
+/// real-time class, which the SMP run queues keep at priority 100 and above.
+pub fn scheduler_priority(nt_priority: u8) -> u8 {
+    if nt_priority >= 16 {
+        100 + (nt_priority - 16)
+    } else {
+        nt_priority * 6
+    }
+}
+
+fn current_thread_id() -> ThreadId {
+    THREAD_MANAGER.lock().get_current_thread().unwrap_or(ThreadId(1))
+}
+
+fn current_process_id() -> ProcessId {
+    use crate::process::PROCESS_MANAGER;
+
+    PROCESS_MANAGER.lock().current_process.unwrap_or(ProcessId(1))
+}
+
+/// Ensure the calling thread has Win32 state and return its id
+fn ensure_current_thread() -> ThreadId {
+    let thread_id = current_thread_id();
+    let process_id = current_process_id();
+    // Without a TEB the thread goes on with no Win32 state
+    let _ = WIN32_THREADS.lock().register_thread(thread_id, process_id, None);
+    thread_id
+}
+
+/// Point the user-mode GS base at a thread's TEB. Called by the scheduler
+/// when switching to `thread_id`; threads without Win32 state are ignored.
+pub fn activate_thread(thread_id: ThreadId) {
+    use x86_64::registers::model_specific::KernelGsBase;
+
+    let teb = match WIN32_THREADS.try_lock() {
+        Some(manager) => manager.thread(thread_id).map(|t| t.teb),
+        None => None,
+    };
+    if let Some(teb) = teb {
+        // Kernel GS base becomes the active GS base after SWAPGS on return
+        // to user mode
+        KernelGsBase::write(VirtAddr::new(teb));
+    }
+}
+
+/// Last-error value of the calling thread, if it has a TEB
+pub fn last_error() -> Option<DWORD> {
+    let thread_id = current_thread_id();
+    WIN32_THREADS.lock().thread(thread_id).map(|t| t.teb().last_error_value)
+}
+
+/// Set the calling thread's last-error value. Returns false without a TEB.
+pub fn set_last_error(error: DWORD) -> bool {
+    let thread_id = current_thread_id();
+    match WIN32_THREADS.lock().thread_mut(thread_id) {
+        Some(thread) => {
+            thread.teb_mut().last_error_value = error;
+            true
+        }
+        None => false,
+    }
+}
+
+fn apply_priority(thread_id: ThreadId) {
+    let Some(base) = WIN32_THREADS.lock().base_priority(thread_id) else {
+        return;
+    };
+    if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+        thread.priority = scheduler_priority(base);
+    }
+}
+
+// Thread API Functions
+
+/// CreateThread - Create a thread in the calling process
+#[no_mangle]
+pub extern "C" fn CreateThread(
+    _thread_attributes: *const u8,
+    stack_size: usize,
+    start_address: Option<ThreadStartRoutine>,
+    parameter: *mut c_void,
+    creation_flags: DWORD,
+    thread_id_out: *mut DWORD,
+) -> HANDLE {
+    let Some(start) = start_address else {
+        super::kernel32::SetLastError(87); // ERROR_INVALID_PARAMETER
+        return Handle::NULL;
+    };
+
+    let process_id = current_process_id();
+    let thread_count = WIN32_THREADS
+        .lock()
+        .threads
+        .values()
+        .filter(|t| t.process_id == process_id)
+        .count() as u64;
+    let limit = crate::security::sandbox::check_resource_limit(
+        process_id.0 as u64,
+        crate::security::sandbox::ResourceType::Threads,
+        thread_count + 1,
+    );
+    if limit.is_err() {
+        super::kernel32::SetLastError(1816); // ERROR_NOT_ENOUGH_QUOTA
+        return Handle::NULL;
+    }
+
+    let stack_size = if stack_size == 0 { DEFAULT_STACK_SIZE } else { (stack_size + 0xFFF) & !0xFFF };
+    let Some(stack) = UserStack::map(process_id, stack_size) else {
+        super::kernel32::SetLastError(8); // ERROR_NOT_ENOUGH_MEMORY
+        return Handle::NULL;
+    };
+    // Entered as if called: a return address slot and the 32-byte home area
+    // of the Win64 convention sit above the first stack pointer
+    let stack_pointer = stack.base - 8 - 32;
+
+    let thread_id = THREAD_MANAGER.lock().create_thread(process_id);
+    {
+        let mut threads = THREAD_MANAGER.lock();
+        if let Some(thread) = threads.get_thread_mut(thread_id) {
+            thread.instruction_pointer = win32_thread_start as usize as u64;
+            thread.stack_pointer = stack_pointer;
+            thread.initial_registers = InitialRegisters {
+                rcx: start as usize as u64,
+                rdx: parameter as u64,
+            };
+            thread.state = ThreadState::Blocked;
+        }
+    }
+
+    let handle = {
+        let mut manager = WIN32_THREADS.lock();
+        let handle = manager.register_thread(thread_id, process_id, Some(stack));
+        if let Some(thread) = manager.thread_mut(thread_id) {
+            if creation_flags & CREATE_SUSPENDED != 0 {
+                thread.suspend_count = 1;
+            }
+        }
+        handle
+    };
+    let Some(handle) = handle else {
+        // No room for its TEB; the thread never runs
+        if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+            thread.state = ThreadState::Terminated;
+        }
+        super::kernel32::SetLastError(8); // ERROR_NOT_ENOUGH_MEMORY
+        return Handle::NULL;
+    };
+    apply_priority(thread_id);
+
+    if creation_flags & CREATE_SUSPENDED == 0 {
+        make_runnable(thread_id);
+    }
+
+    if !thread_id_out.is_null() {
+        unsafe { *thread_id_out = thread_id.0; }
+    }
+    handle
+}
+
+fn make_runnable(thread_id: ThreadId) {
+    if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+        thread.state = ThreadState::Ready;
+    }
+    crate::process::smp_scheduler::enqueue_thread(thread_id);
+}
+
+/// Entry point of every thread created through CreateThread. The routine
+/// and its argument arrive in RCX and RDX from the initial registers.
+extern "win64" fn win32_thread_start(start: u64, parameter: u64) -> ! {
+    super::loader::notify_thread(super::loader::DLL_THREAD_ATTACH);
+    let exit_code = if start != 0 {
+        let routine: ThreadStartRoutine = unsafe { core::mem::transmute(start as usize) };
+        routine(parameter as *mut c_void)
+    } else {
+        0
+    };
+    ExitThread(exit_code)
+}
+
+/// ExitThread - Terminate the calling thread
+#[no_mangle]
+pub extern "C" fn ExitThread(exit_code: DWORD) -> ! {
+    let thread_id = current_thread_id();
+    super::loader::notify_thread(super::loader::DLL_THREAD_DETACH);
+    if let Some(thread) = WIN32_THREADS.lock().thread_mut(thread_id) {
+        thread.exit_code = Some(exit_code);
+    }
+    super::message::MESSAGE_QUEUES.lock().destroy_queue(thread_id.0);
+    if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+        thread.state = ThreadState::Terminated;
+    }
+    crate::process::smp_scheduler::dequeue_thread(thread_id);
+    loop {
+        crate::process::smp_scheduler::schedule();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// GetExitCodeThread - Get a thread's exit status
+#[no_mangle]
+pub extern "C" fn GetExitCodeThread(handle: HANDLE, exit_code: *mut DWORD) -> BOOL {
+    if exit_code.is_null() {
+        return 0;
+    }
+    let manager = WIN32_THREADS.lock();
+    let Some(thread) = manager.resolve_handle(handle).and_then(|id| manager.thread(id)) else {
+        return 0;
+    };
+    unsafe { *exit_code = thread.exit_code.unwrap_or(STILL_ACTIVE); }
+    1
+}
+
+/// SuspendThread - Increment a thread's suspend count
+#[no_mangle]
+pub extern "C" fn SuspendThread(handle: HANDLE) -> DWORD {
+    let (thread_id, previous) = {
+        let mut manager = WIN32_THREADS.lock();
+        let Some(thread_id) = manager.resolve_handle(handle) else {
+            return 0xFFFFFFFF;
+        };
+        let Some(thread) = manager.thread_mut(thread_id) else {
+            return 0xFFFFFFFF;
+        };
+        let previous = thread.suspend_count;
+        if previous >= MAXIMUM_SUSPEND_COUNT {
+            return 0xFFFFFFFF;
+        }
+        thread.suspend_count += 1;
+        (thread_id, previous)
+    };
+
+    if previous == 0 {
+        if let Some(thread) = THREAD_MANAGER.lock().get_thread_mut(thread_id) {
+            thread.state = ThreadState::Blocked;
+        }
+        crate::process::smp_scheduler::dequeue_thread(thread_id);
+    }
+    previous
+}
+
+/// ResumeThread - Decrement a thread's suspend count
+#[no_mangle]
+pub extern "C" fn ResumeThread(handle: HANDLE) -> DWORD {
+    let (thread_id, previous) = {
+        let mut manager = WIN32_THREADS.lock();
+        let Some(thread_id) = manager.resolve_handle(handle) else {
+            return 0xFFFFFFFF;
+        };
+        let Some(thread) = manager.thread_mut(thread_id) else {
+            return 0xFFFFFFFF;
+        };
+        let previous = thread.suspend_count;
+        thread.suspend_count = previous.saturating_sub(1);
+        (thread_id, previous)
+    };
+
+    if previous == 1 {
+        make_runnable(thread_id);
+    }
+    previous
+}
+
+/// GetCurrentThread - Pseudo handle for the calling thread
+#[no_mangle]
+pub extern "C" fn GetCurrentThread() -> HANDLE {
+    CURRENT_THREAD_HANDLE
+}
+
+/// GetThreadId - Thread identifier for a thread handle
+#[no_mangle]
+pub extern "C" fn GetThreadId(handle: HANDLE) -> DWORD {
+    WIN32_THREADS.lock().resolve_handle(handle).map_or(0, |id| id.0)
+}
+
+/// SetThreadPriority - Set priority relative to the process class
+#[no_mangle]
+pub extern "C" fn SetThreadPriority(handle: HANDLE, priority: i32) -> BOOL {
+    let valid = matches!(priority, THREAD_PRIORITY_IDLE | THREAD_PRIORITY_TIME_CRITICAL)
+        || (THREAD_PRIORITY_LOWEST..=THREAD_PRIORITY_HIGHEST).contains(&priority);
+    if !valid {
+        super::kernel32::SetLastError(87); // ERROR_INVALID_PARAMETER
+        return 0;
+    }
+
+    ensure_current_thread();
+    let thread_id = {
+        let mut manager = WIN32_THREADS.lock();
+        let Some(thread_id) = manager.resolve_handle(handle) else {
+            return 0;
+        };
+        match manager.thread_mut(thread_id) {
+            Some(thread) => thread.relative_priority = priority,
+            None => return 0,
+        }
+        thread_id
+    };
+    apply_priority(thread_id);
+    1
+}
+
+/// GetThreadPriority - Get priority relative to the process class
+#[no_mangle]
+pub extern "C" fn GetThreadPriority(handle: HANDLE) -> i32 {
+    ensure_current_thread();
+    let manager = WIN32_THREADS.lock();
+    manager
+        .resolve_handle(handle)
+        .and_then(|id| manager.thread(id))
+        .map_or(THREAD_PRIORITY_ERROR_RETURN, |t| t.relative_priority)
+}
+
+/// SetPriorityClass - Set the priority class of the calling process
+#[no_mangle]
+pub extern "C" fn SetPriorityClass(_process: HANDLE, priority_class: DWORD) -> BOOL {
+    let valid = matches!(
+        priority_class,
+        IDLE_PRIORITY_CLASS | BELOW_NORMAL_PRIORITY_CLASS | NORMAL_PRIORITY_CLASS
+            | ABOVE_NORMAL_PRIORITY_CLASS | HIGH_PRIORITY_CLASS | REALTIME_PRIORITY_CLASS
+    );
+    if !valid {
+        return 0;
+    }
+
+    let process_id = current_process_id();
+    let threads: Vec<ThreadId> = {
+        let mut manager = WIN32_THREADS.lock();
+        match manager.process_mut(process_id) {
+            Some(process) => process.priority_class = priority_class,
+            None => return 0,
+        }
+        manager
+            .threads
+            .values()
+            .filter(|t| t.process_id == process_id)
+            .map(|t| t.thread_id)
+            .collect()
+    };
+    for thread_id in threads {
+        apply_priority(thread_id);
+    }
+    1
+}
+
+/// GetPriorityClass - Get the priority class of the calling process
+#[no_mangle]
+pub extern "C" fn GetPriorityClass(_process: HANDLE) -> DWORD {
+    WIN32_THREADS
+        .lock()
+        .process_mut(current_process_id())
+        .map_or(NORMAL_PRIORITY_CLASS, |process| process.priority_class)
+}
+
+/// SwitchToThread - Yield the rest of the time slice
+#[no_mangle]
+pub extern "C" fn SwitchToThread() -> BOOL {
+    crate::process::smp_scheduler::yield_current();
+    1
+}
+
+// Thread-local storage
+
+/// TlsAlloc - Allocate a TLS index
+#[no_mangle]
+pub extern "C" fn TlsAlloc() -> DWORD {
+    ensure_current_thread();
+    match WIN32_THREADS.lock().tls_alloc(current_process_id()) {
+        Some(index) => index as DWORD,
+        None => {
+            super::kernel32::SetLastError(ERROR_NO_MORE_ITEMS);
+            TLS_OUT_OF_INDEXES
+        }
+    }
+}
+
+/// TlsFree - Release a TLS index
+#[no_mangle]
+pub extern "C" fn TlsFree(index: DWORD) -> BOOL {
+    WIN32_THREADS.lock().tls_free(current_process_id(), index as usize) as BOOL
+}
+
+/// TlsGetValue - Read the calling thread's value for a TLS index
+#[no_mangle]
+pub extern "C" fn TlsGetValue(index: DWORD) -> *mut c_void {
+    let thread_id = ensure_current_thread();
+    let mut manager = WIN32_THREADS.lock();
+    if !manager.tls_in_use(current_process_id(), index as usize) {
+        drop(manager);
+        super::kernel32::SetLastError(87); // ERROR_INVALID_PARAMETER
+        return core::ptr::null_mut();
+    }
+    let Some(thread) = manager.thread_mut(thread_id) else {
+        return core::ptr::null_mut();
+    };
+    let value = thread.get_tls(index as usize);
+    // A successful read clears the last error, as on Windows
+    thread.teb_mut().last_error_value = ERROR_SUCCESS;
+    value as *mut c_void
+}
+
+/// TlsSetValue - Store the calling thread's value for a TLS index
+#[no_mangle]
+pub extern "C" fn TlsSetValue(index: DWORD, value: *mut c_void) -> BOOL {
+    let thread_id = ensure_current_thread();
+    let mut manager = WIN32_THREADS.lock();
+    if !manager.tls_in_use(current_process_id(), index as usize) {
+        return 0;
+    }
+    match manager.thread_mut(thread_id) {
+        Some(thread) => {
+            if thread.set_tls(index as usize, value as u64) {
+                return 1;
+            }
+            drop(manager);
+            super::kernel32::SetLastError(8); // ERROR_NOT_ENOUGH_MEMORY
+            0
+        }
+        None => 0,
+    }
+}
+
+// Fibers
+
+core::arch::global_asm!(
+    ".global win32_fiber_switch",
+    "win32_fiber_switch:",
+    "mov [rdi + 0x00], rsp",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x10], rbp",
+    "mov [rdi + 0x18], r12",
+    "mov [rdi + 0x20], r13",
+    "mov [rdi + 0x28], r14",
+    "mov [rdi + 0x30], r15",
+    "mov rsp, [rsi + 0x00]",
+    "mov rbx, [rsi + 0x08]",
+    "mov rbp, [rsi + 0x10]",
+    "mov r12, [rsi + 0x18]",
+    "mov r13, [rsi + 0x20]",
+    "mov r14, [rsi + 0x28]",
+    "mov r15, [rsi + 0x30]",
+    "ret",
+    // First entry into a new fiber: r12 = start routine, r13 = parameter
+    ".global win32_fiber_start",
+    "win32_fiber_start:",
+    "mov rdi, r13",
+    "call r12",
+    "call win32_fiber_exit",
+    "ud2",
+);
+
+extern "C" {
+    fn win32_fiber_switch(from: *mut FiberContext, to: *const FiberContext);
+    fn win32_fiber_start();
+}
+
+/// A fiber routine returning ends its thread, as on Windows
+#[no_mangle]
+extern "C" fn win32_fiber_exit() -> ! {
+    ExitThread(0)
+}
+
+/// ConvertThreadToFiber - Turn the calling thread into a fiber
+#[no_mangle]
+pub extern "C" fn ConvertThreadToFiber(parameter: *mut c_void) -> *mut c_void {
+    let thread_id = ensure_current_thread();
+    let mut manager = WIN32_THREADS.lock();
+    let Some(thread) = manager.thread(thread_id) else {
+        return core::ptr::null_mut();
+    };
+    if thread.current_fiber.is_some() {
+        drop(manager);
+        super::kernel32::SetLastError(ERROR_ALREADY_FIBER);
+        return core::ptr::null_mut();
+    }
+    let (stack_base, stack_limit) = (thread.teb().nt_tib.stack_base, thread.teb().nt_tib.stack_limit);
+
+    let fiber = Box::new(Fiber {
+        context: FiberContext::default(),
+        parameter: parameter as u64,
+        stack: None,
+        stack_base,
+        stack_limit,
+        running_on: Some(thread_id),
+    });
+    let address = &*fiber as *const Fiber as u64;
+    manager.fibers.insert(address, fiber);
+    if let Some(thread) = manager.thread_mut(thread_id) {
+        thread.current_fiber = Some(address);
+        thread.teb_mut().nt_tib.fiber_data = address;
+    }
+    address as *mut c_void
+}
+
+/// ConvertFiberToThread - Release the fiber state of the calling thread
+#[no_mangle]
+pub extern "C" fn ConvertFiberToThread() -> BOOL {
+    let thread_id = current_thread_id();
+    let mut manager = WIN32_THREADS.lock();
+    let Some(address) = manager.thread(thread_id).and_then(|t| t.current_fiber) else {
+        return 0;
+    };
+    manager.fibers.remove(&address);
+    if let Some(thread) = manager.thread_mut(thread_id) {
+        thread.current_fiber = None;
+        thread.teb_mut().nt_tib.fiber_data = 0;
+    }
+    1
+}
+
+/// CreateFiber - Create a fiber with its own stack
+#[no_mangle]
+pub extern "C" fn CreateFiber(
+    stack_size: usize,
+    start_address: Option<FiberStartRoutine>,
+    parameter: *mut c_void,
+) -> *mut c_void {
+    let Some(start) = start_address else {
+        return core::ptr::null_mut();
+    };
+
+    let stack_size = if stack_size == 0 { FIBER_STACK_SIZE } else { (stack_size + 0xF) & !0xF };
+    let Some(stack) = UserStack::map(current_process_id(), stack_size) else {
+        super::kernel32::SetLastError(8); // ERROR_NOT_ENOUGH_MEMORY
+        return core::ptr::null_mut();
+    };
+    let (stack_base, stack_limit) = (stack.base & !0xF, stack.limit);
+
+    // `ret` in win32_fiber_switch pops the entry trampoline; the slot is
+    // placed so the stack is 16-byte aligned at the call into the routine
+    let initial_rsp = stack_base - 24;
+    unsafe {
+        *(initial_rsp as *mut u64) = win32_fiber_start as usize as u64;
+    }
+
+    let fiber = Box::new(Fiber {
+        context: FiberContext {
+            rsp: initial_rsp,
+            r12: start as usize as u64,
+            r13: parameter as u64,
+            ..FiberContext::default()
+        },
+        parameter: parameter as u64,
+        stack: Some(stack),
+        stack_base,
+        stack_limit,
+        running_on: None,
+    });
+    let address = &*fiber as *const Fiber as u64;
+    WIN32_THREADS.lock().fibers.insert(address, fiber);
+    address as *mut c_void
+}
+
+/// DeleteFiber - Free a fiber that is not running
+#[no_mangle]
+pub extern "C" fn DeleteFiber(fiber: *mut c_void) {
+    let address = fiber as u64;
+    let current = current_thread_id();
+    let deleting_self = {
+        let mut manager = WIN32_THREADS.lock();
+        let running = manager.fiber(address).and_then(|f| f.running_on);
+        match running {
+            Some(thread_id) if thread_id == current => true,
+            Some(_) => false, // Running on another thread; leave it alone
+            None => {
+                manager.fibers.remove(&address);
+                false
+            }
+        }
+    };
+    // Deleting the running fiber terminates the thread
+    if deleting_self {
+        ExitThread(0);
+    }
+}
+
+/// SwitchToFiber - Save the current fiber and resume another
+#[no_mangle]
+pub extern "C" fn SwitchToFiber(fiber: *mut c_void) {
+    let thread_id = current_thread_id();
+    let target = fiber as u64;
+
+    let (from, to) = {
+        let mut manager = WIN32_THREADS.lock();
+        let Some(current) = manager.thread(thread_id).and_then(|t| t.current_fiber) else {
+            return;
+        };
+        if current == target {
+            return;
+        }
+        let (to, stack_base, stack_limit) = match manager.fiber(target) {
+            Some(f) if f.running_on.is_none() => {
+                f.running_on = Some(thread_id);
+                (&f.context as *const FiberContext, f.stack_base, f.stack_limit)
+            }
+            _ => return,
+        };
+        let from = match manager.fiber(current) {
+            Some(f) => {
+                f.running_on = None;
+                &mut f.context as *mut FiberContext
+            }
+            None => return,
+        };
+        if let Some(thread) = manager.thread_mut(thread_id) {
+            thread.current_fiber = Some(target);
+            let teb = thread.teb_mut();
+            teb.nt_tib.fiber_data = target;
+            teb.nt_tib.stack_base = stack_base;
+            teb.nt_tib.stack_limit = stack_limit;
+        }
+        (from, to)
+    };
+
+    // Fibers are boxed, so the context pointers stay valid after the lock
+    // is released
+    unsafe { win32_fiber_switch(from, to) };
+}
+
+/// GetCurrentFiber - Address of the running fiber
+#[no_mangle]
+pub extern "C" fn GetCurrentFiber() -> *mut c_void {
+    let thread_id = current_thread_id();
+    WIN32_THREADS
+        .lock()
+        .thread(thread_id)
+        .and_then(|t| t.current_fiber)
+        .map_or(core::ptr::null_mut(), |a| a as *mut c_void)
+}
+
+/// GetFiberData - Parameter passed when the running fiber was created
+#[no_mangle]
+pub extern "C" fn GetFiberData() -> *mut c_void {
+    let thread_id = current_thread_id();
+    let mut manager = WIN32_THREADS.lock();
+    let Some(address) = manager.thread(thread_id).and_then(|t| t.current_fiber) else {
+        return core::ptr::null_mut();
+    };
+    manager.fiber(address).map_or(core::ptr::null_mut(), |f| f.parameter as *mut c_void)
+}
+
+/// IsThreadAFiber - Whether the calling thread has been converted to a fiber
+#[no_mangle]
+pub extern "C" fn IsThreadAFiber() -> BOOL {
+    let thread_id = current_thread_id();
+    WIN32_THREADS
+        .lock()
+        .thread(thread_id)
+        .map_or(false, |t| t.current_fiber.is_some()) as BOOL
+}
+
+pub const ERROR_NO_MORE_ITEMS: DWORD = 259;
+pub const ERROR_ALREADY_FIBER: DWORD = 1280;
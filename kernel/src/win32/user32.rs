@@ -1,5 +1,10 @@
 use super::*;
+use super::gdi::{IconObject, GDI_MANAGER};
+use super::resource::{self, module_image, Icon, ResourceName, ERROR_RESOURCE_NAME_NOT_FOUND};
+use crate::clipboard::Image;
+use alloc::collections::BTreeMap;
 use core::ffi::CStr;
+use spin::Mutex;
 
 /// MessageBoxA - Display a message box (ANSI version)
 #[no_mangle]
@@ -82,7 +87,288 @@ pub extern "C" fn ShowWindow(
 
 /// UpdateWindow - Update a window
 #[no_mangle]
-pub extern "C" fn UpdateWindow(_hwnd: HANDLE) -> BOOL {
-    // Placeholder implementation
-    1 // TRUE
-}
\ No newline at end of file
+pub extern "C" fn UpdateWindow(hwnd: HANDLE) -> BOOL {
+    if super::message::update_window(hwnd) {
+        1 // TRUE
+    } else {
+        0 // FALSE
+    }
+}
+
+// Icons, cursors, bitmaps and strings from a module's resources; see
+// `resource`. Icons are not scaled: the size nearest the one asked for is
+// taken as it is.
+
+const ERROR_INVALID_PARAMETER: DWORD = 87;
+const ERROR_INVALID_DATA: DWORD = 13;
+
+// LoadImage's types and flags
+pub const IMAGE_BITMAP: u32 = 0;
+pub const IMAGE_ICON: u32 = 1;
+pub const IMAGE_CURSOR: u32 = 2;
+pub const LR_LOADFROMFILE: u32 = 0x0010;
+pub const LR_SHARED: u32 = 0x8000;
+
+// IDC_ARROW, the first stock cursor, to IDC_HELP, the last; the stock
+// icons, IDI_APPLICATION to IDI_SHIELD, are among them
+const FIRST_STOCK_ID: u16 = 32512;
+const LAST_STOCK_ID: u16 = 32651;
+
+// SM_CXICON, the size LoadIcon and LoadCursor load
+const ICON_SIZE: u32 = 32;
+
+// Images loaded shared, by (module, image type, name); they are kept for
+// good, and DestroyIcon leaves them
+static SHARED: Mutex<BTreeMap<(u64, u32, ResourceName), HANDLE>> = Mutex::new(BTreeMap::new());
+
+fn fail<T>(error: DWORD, value: T) -> T {
+    super::kernel32::SetLastError(error);
+    value
+}
+
+fn icon_handle(icon: Icon, cursor: bool) -> HANDLE {
+    let Icon { image, hotspot } = icon;
+    GDI_MANAGER.lock().create_icon(IconObject {
+        width: image.width as i32,
+        height: image.height as i32,
+        hotspot: (hotspot.0 as i32, hotspot.1 as i32),
+        cursor,
+        pixels: image.pixels,
+    })
+}
+
+fn bitmap_handle(image: Image) -> HANDLE {
+    let data = image.pixels.iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+    GDI_MANAGER.lock().create_bitmap(image.width as i32, image.height as i32, 32, data)
+}
+
+// The stock icons and cursors, drawn here: every icon is a window, every
+// cursor an arrow pointing up and left
+fn stock_icon(cursor: bool) -> Icon {
+    const SIZE: usize = 32;
+    const CLEAR: u32 = 0;
+    const BLACK: u32 = 0xFF00_0000;
+    const WHITE: u32 = 0xFFFF_FFFF;
+    const NAVY: u32 = 0xFF00_0080;
+    let pixel = |x: usize, y: usize| match cursor {
+        true if y >= 16 || x > y => CLEAR,
+        true if x == 0 || x == y || y == 15 => BLACK,
+        true => WHITE,
+        false if !(2..=29).contains(&x) || !(4..=27).contains(&y) => CLEAR,
+        false if x == 2 || x == 29 || y == 4 || y == 27 => BLACK,
+        false if y < 10 => NAVY,
+        false => WHITE,
+    };
+    let pixels = (0..SIZE * SIZE).map(|i| pixel(i % SIZE, i / SIZE)).collect();
+    Icon { image: Image { width: SIZE, height: SIZE, pixels }, hotspot: (0, 0) }
+}
+
+// An icon, cursor or bitmap of `module` nearest `size` pixels; a NULL
+// module has the stock icons and cursors. With `shared`, a name loaded
+// before gives the same handle.
+fn load_image(module: HANDLE, name: Option<ResourceName>, kind: u32, size: u32, shared: bool) -> HANDLE {
+    let Some(name) = name else {
+        return fail(ERROR_INVALID_PARAMETER, Handle::NULL);
+    };
+    let key = (module.0, kind, name);
+    if shared {
+        if let Some(&handle) = SHARED.lock().get(&key) {
+            return handle;
+        }
+    }
+
+    let (name, cursor) = (&key.2, kind == IMAGE_CURSOR);
+    let loaded = match kind {
+        IMAGE_ICON | IMAGE_CURSOR if module == Handle::NULL => match name {
+            ResourceName::Id(FIRST_STOCK_ID..=LAST_STOCK_ID) => Ok(icon_handle(stock_icon(cursor), cursor)),
+            _ => Err(ERROR_RESOURCE_NAME_NOT_FOUND),
+        },
+        IMAGE_ICON | IMAGE_CURSOR => {
+            module_image(module).and_then(|image| image.icon(name, cursor, size)).map(|icon| icon_handle(icon, cursor))
+        }
+        IMAGE_BITMAP => module_image(module).and_then(|image| image.bitmap(name)).map(bitmap_handle),
+        _ => Err(ERROR_INVALID_PARAMETER),
+    };
+    let handle = match loaded {
+        Ok(handle) => handle,
+        Err(error) => return fail(error, Handle::NULL),
+    };
+    if !shared {
+        return handle;
+    }
+    // Another thread may have loaded it meanwhile
+    let first = *SHARED.lock().entry(key).or_insert(handle);
+    if first != handle {
+        GDI_MANAGER.lock().delete_object(handle);
+    }
+    first
+}
+
+// LoadImage from a BMP, ICO or CUR file
+fn load_image_file(path: &str, kind: u32, size: u32) -> HANDLE {
+    let data = match crate::fs::vfs::VFS.lock().read_file(path) {
+        Ok(data) => data,
+        Err(error) => return fail(super::advapi32::file_error(error), Handle::NULL),
+    };
+    let cursor = kind == IMAGE_CURSOR;
+    let handle = match kind {
+        IMAGE_BITMAP => resource::decode_bmp(&data).map(bitmap_handle),
+        IMAGE_ICON | IMAGE_CURSOR => resource::decode_ico(&data, size).map(|icon| icon_handle(icon, cursor)),
+        _ => return fail(ERROR_INVALID_PARAMETER, Handle::NULL),
+    };
+    handle.unwrap_or_else(|| fail(ERROR_INVALID_DATA, Handle::NULL))
+}
+
+fn load_image_ex(module: HANDLE, name: Option<ResourceName>, kind: u32, width: i32, flags: u32) -> HANDLE {
+    let size = if width > 0 { width as u32 } else { ICON_SIZE };
+    if flags & LR_LOADFROMFILE == 0 {
+        return load_image(module, name, kind, size, flags & LR_SHARED != 0);
+    }
+    match name {
+        Some(ResourceName::Name(path)) => load_image_file(&path, kind, size),
+        _ => fail(ERROR_INVALID_PARAMETER, Handle::NULL),
+    }
+}
+
+/// LoadIconA - Load an icon of a module, or a stock icon for a NULL
+/// module, at the system's icon size
+#[no_mangle]
+pub extern "C" fn LoadIconA(instance: HANDLE, name: LPCSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_ansi(name) }, IMAGE_ICON, ICON_SIZE, true)
+}
+
+/// LoadIconW - Load an icon of a module, or a stock icon for a NULL
+/// module (wide version)
+#[no_mangle]
+pub extern "C" fn LoadIconW(instance: HANDLE, name: LPCWSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_wide(name) }, IMAGE_ICON, ICON_SIZE, true)
+}
+
+/// LoadCursorA - Load a cursor of a module, or a stock cursor for a NULL
+/// module
+#[no_mangle]
+pub extern "C" fn LoadCursorA(instance: HANDLE, name: LPCSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_ansi(name) }, IMAGE_CURSOR, ICON_SIZE, true)
+}
+
+/// LoadCursorW - Load a cursor of a module, or a stock cursor for a NULL
+/// module (wide version)
+#[no_mangle]
+pub extern "C" fn LoadCursorW(instance: HANDLE, name: LPCWSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_wide(name) }, IMAGE_CURSOR, ICON_SIZE, true)
+}
+
+/// LoadBitmapA - Load a bitmap of a module as a 32-bit bitmap, which the
+/// caller deletes with DeleteObject
+#[no_mangle]
+pub extern "C" fn LoadBitmapA(instance: HANDLE, name: LPCSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_ansi(name) }, IMAGE_BITMAP, 0, false)
+}
+
+/// LoadBitmapW - Load a bitmap of a module (wide version)
+#[no_mangle]
+pub extern "C" fn LoadBitmapW(instance: HANDLE, name: LPCWSTR) -> HANDLE {
+    load_image(instance, unsafe { ResourceName::from_wide(name) }, IMAGE_BITMAP, 0, false)
+}
+
+/// LoadImageA - Load a bitmap, icon or cursor from a module, or with
+/// LR_LOADFROMFILE from a BMP, ICO or CUR file; icons come at the size
+/// nearest `width`
+#[no_mangle]
+pub extern "C" fn LoadImageA(
+    instance: HANDLE,
+    name: LPCSTR,
+    kind: u32,
+    width: i32,
+    _height: i32,
+    flags: u32,
+) -> HANDLE {
+    load_image_ex(instance, unsafe { ResourceName::from_ansi(name) }, kind, width, flags)
+}
+
+/// LoadImageW - Load a bitmap, icon or cursor (wide version)
+#[no_mangle]
+pub extern "C" fn LoadImageW(
+    instance: HANDLE,
+    name: LPCWSTR,
+    kind: u32,
+    width: i32,
+    _height: i32,
+    flags: u32,
+) -> HANDLE {
+    load_image_ex(instance, unsafe { ResourceName::from_wide(name) }, kind, width, flags)
+}
+
+/// DestroyIcon - Destroy an icon that was not loaded shared
+#[no_mangle]
+pub extern "C" fn DestroyIcon(icon: HANDLE) -> BOOL {
+    if SHARED.lock().values().any(|&shared| shared == icon) {
+        return 1;
+    }
+    let mut gdi = GDI_MANAGER.lock();
+    if gdi.icon(icon).is_none() {
+        return fail(ERROR_INVALID_HANDLE, 0);
+    }
+    gdi.delete_object(icon) as BOOL
+}
+
+/// DestroyCursor - Destroy a cursor that was not loaded shared
+#[no_mangle]
+pub extern "C" fn DestroyCursor(cursor: HANDLE) -> BOOL {
+    DestroyIcon(cursor)
+}
+
+/// LoadStringA - Copy string `id` of a module's string table into
+/// `buffer`, NUL-terminated and cut to fit at a character; returns its
+/// length in bytes
+#[no_mangle]
+pub extern "C" fn LoadStringA(instance: HANDLE, id: u32, buffer: LPSTR, buffer_max: i32) -> i32 {
+    if buffer.is_null() || buffer_max <= 0 {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    let text = match module_image(instance) {
+        Ok(image) => image.string(id as u16, None),
+        Err(error) => return fail(error, 0),
+    };
+    let Some(text) = text else {
+        return fail(ERROR_RESOURCE_NAME_NOT_FOUND, 0);
+    };
+    let mut len = text.len().min(buffer_max as usize - 1);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(text.as_ptr(), buffer, len);
+        *buffer.add(len) = 0;
+    }
+    len as i32
+}
+
+/// LoadStringW - Copy string `id` of a module's string table into
+/// `buffer` (wide version); with a `buffer_max` of 0, store a pointer to
+/// the string in the image instead, which is not NUL-terminated
+#[no_mangle]
+pub extern "C" fn LoadStringW(instance: HANDLE, id: u32, buffer: LPWSTR, buffer_max: i32) -> i32 {
+    if buffer.is_null() || buffer_max < 0 {
+        return fail(ERROR_INVALID_PARAMETER, 0);
+    }
+    let data = match module_image(instance) {
+        Ok(image) => image.string_data(id as u16, None),
+        Err(error) => return fail(error, 0),
+    };
+    let Some(data) = data else {
+        return fail(ERROR_RESOURCE_NAME_NOT_FOUND, 0);
+    };
+    let units = data.len() / 2;
+    if buffer_max == 0 {
+        unsafe { *(buffer as *mut *const u8) = data.as_ptr() };
+        return units as i32;
+    }
+    let len = units.min(buffer_max as usize - 1);
+    // Copied as bytes, as the image need not keep the string aligned
+    unsafe {
+        core::ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, len * 2);
+        *buffer.add(len) = 0;
+    }
+    len as i32
+}
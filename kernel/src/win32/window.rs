@@ -57,6 +57,25 @@ impl WindowRect {
     pub fn contains(&self, x: i32, y: i32) -> bool {
         x >= self.left && x <= self.right && y >= self.top && y <= self.bottom
     }
+    
+    pub fn is_empty(&self) -> bool {
+        self.right <= self.left || self.bottom <= self.top
+    }
+    
+    pub fn union(&self, other: &WindowRect) -> WindowRect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        WindowRect::new(
+            self.left.min(other.left),
+            self.top.min(other.top),
+            self.right.max(other.right),
+            self.bottom.max(other.bottom),
+        )
+    }
 }
 
 // Window procedure type
@@ -86,7 +105,6 @@ pub struct WindowManager {
     active_window: Option<HANDLE>,
     capture_window: Option<HANDLE>,
     focus_window: Option<HANDLE>,
-    message_queue: Vec<Message>,
 }
 
 // Window message
@@ -121,7 +139,6 @@ impl WindowManager {
             active_window: None,
             capture_window: None,
             focus_window: None,
-            message_queue: Vec::new(),
         };
         
         // Create desktop window
@@ -298,6 +315,9 @@ impl WindowManager {
             }
         }
         
+        // Drop posted messages, paint state and timers for the window
+        super::message::MESSAGE_QUEUES.lock().purge_window(hwnd);
+        
         self.windows.remove(&hwnd.0).is_some()
     }
     
@@ -375,20 +395,17 @@ impl WindowManager {
         default_window_proc(hwnd, msg, wparam, lparam)
     }
     
-    pub fn post_message(&mut self, hwnd: HANDLE, msg: u32, wparam: usize, lparam: isize) {
-        let message = Message {
-            hwnd,
-            message: msg,
-            wparam,
-            lparam,
-            time: 0, // TODO: Get tick count
-            point: Point { x: 0, y: 0 },
-        };
-        self.message_queue.push(message);
+    pub fn window_proc(&self, hwnd: HANDLE) -> Option<WindowProc> {
+        let window = self.windows.get(&hwnd.0)?;
+        Some(window.wnd_proc.unwrap_or(default_window_proc))
+    }
+    
+    pub fn window_thread(&self, hwnd: HANDLE) -> Option<DWORD> {
+        self.windows.get(&hwnd.0).map(|w| w.thread_id)
     }
     
-    pub fn get_message(&mut self) -> Option<Message> {
-        self.message_queue.pop()
+    pub fn client_rect(&self, hwnd: HANDLE) -> Option<WindowRect> {
+        self.windows.get(&hwnd.0).map(|w| w.client_rect)
     }
     
     pub fn set_active_window(&mut self, hwnd: HANDLE) -> Option<HANDLE> {
@@ -659,7 +676,7 @@ pub extern "C" fn SendMessageA(
     wparam: usize,
     lparam: isize,
 ) -> isize {
-    WINDOW_MANAGER.lock().send_message(hwnd, msg, wparam, lparam)
+    super::message::send_message(hwnd, msg, wparam, lparam)
 }
 
 /// PostMessageA - Post a message to a window
@@ -670,8 +687,11 @@ pub extern "C" fn PostMessageA(
     wparam: usize,
     lparam: isize,
 ) -> BOOL {
-    WINDOW_MANAGER.lock().post_message(hwnd, msg, wparam, lparam);
-    1
+    if super::message::post_message(hwnd, msg, wparam, lparam) {
+        1
+    } else {
+        0
+    }
 }
 
 /// SetActiveWindow - Set the active window
@@ -687,7 +707,7 @@ pub extern "C" fn SetFocus(hwnd: HANDLE) -> HANDLE {
 }
 
 // Helper functions to get current thread and process IDs
-fn get_current_thread_id() -> DWORD {
+pub(super) fn get_current_thread_id() -> DWORD {
     use crate::process::thread::THREAD_MANAGER;
     
     if let Some(thread_id) = THREAD_MANAGER.lock().get_current_thread() {
@@ -353,6 +353,22 @@ fn protocol_to_network(protocol: i32) -> NetworkProtocol {
     }
 }
 
+fn current_pid() -> Option<u64> {
+    crate::process::PROCESS_MANAGER.lock().current_process.map(|pid| pid.0 as u64)
+}
+
+// Sandboxed processes only reach the addresses their profile allows
+fn sandbox_allows(socket_addr: &SocketAddr, outbound: bool) -> bool {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return true,
+    };
+    let address = format!("{}.{}.{}.{}",
+                          socket_addr.ip.octets[0], socket_addr.ip.octets[1],
+                          socket_addr.ip.octets[2], socket_addr.ip.octets[3]);
+    crate::security::sandbox::check_network_access(pid, &address, socket_addr.port, outbound).is_ok()
+}
+
 // Winsock API Functions
 
 /// Initialize the Winsock library
@@ -433,6 +449,13 @@ pub extern "C" fn socket(af: i32, socket_type: i32, protocol: i32) -> Handle {
         return INVALID_SOCKET;
     }
     
+    if let Some(pid) = current_pid() {
+        if crate::security::sandbox::check_network_enabled(pid).is_err() {
+            set_last_error(WSAEACCES);
+            return INVALID_SOCKET;
+        }
+    }
+    
     let sock_type = match socket_type_to_network(socket_type) {
         Some(t) => t,
         None => {
@@ -486,6 +509,10 @@ pub extern "C" fn bind(socket: Handle, addr: *const SockAddr, addr_len: i32) ->
         }
         
         let socket_addr = sock_addr.to_socket_addr();
+        if !sandbox_allows(&socket_addr, false) {
+            set_last_error(WSAEACCES);
+            return SOCKET_ERROR;
+        }
         match network_bind_socket(socket, socket_addr) {
             NtStatus::Success => {
                 crate::println!("Winsock: Bound socket {:?} to {}.{}.{}.{}:{}", 
@@ -534,6 +561,10 @@ pub extern "C" fn connect(socket: Handle, addr: *const SockAddr, addr_len: i32)
         }
         
         let socket_addr = sock_addr.to_socket_addr();
+        if !sandbox_allows(&socket_addr, true) {
+            set_last_error(WSAEACCES);
+            return SOCKET_ERROR;
+        }
         match network_connect_socket(socket, socket_addr) {
             NtStatus::Success => {
                 crate::println!("Winsock: Connected socket {:?} to {}.{}.{}.{}:{}", 
@@ -0,0 +1,244 @@
+//! XInput
+//!
+//! The pads in `input::gamepad`'s four player slots, as xinput1_4.dll,
+//! xinput1_3.dll and xinput9_1_0.dll offer them. The layouts match, so a
+//! state is copied over as it is, less the guide button, which
+//! XInputGetState never reports. Every pad is a wired gamepad.
+//!
+//! XInputEnable(FALSE) makes every pad read as at rest and stops the
+//! motors; the speeds set while it is off take effect when it is turned
+//! back on. Keystrokes are not queued, so XInputGetKeystroke always finds
+//! none.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{BOOL, DWORD, ERROR_SUCCESS};
+use crate::input::gamepad::{self, MAX_PADS};
+
+pub const ERROR_BAD_ARGUMENTS: DWORD = 160;
+pub const ERROR_DEVICE_NOT_CONNECTED: DWORD = 1167;
+pub const ERROR_EMPTY: DWORD = 4306;
+
+pub const XINPUT_DEVTYPE_GAMEPAD: u8 = 0x01;
+pub const XINPUT_DEVSUBTYPE_GAMEPAD: u8 = 0x01;
+pub const XINPUT_FLAG_GAMEPAD: DWORD = 0x0000_0001;
+
+pub const BATTERY_DEVTYPE_GAMEPAD: u8 = 0x00;
+pub const BATTERY_DEVTYPE_HEADSET: u8 = 0x01;
+pub const BATTERY_TYPE_DISCONNECTED: u8 = 0x00;
+pub const BATTERY_TYPE_WIRED: u8 = 0x01;
+pub const BATTERY_LEVEL_EMPTY: u8 = 0x00;
+pub const BATTERY_LEVEL_FULL: u8 = 0x03;
+
+// The buttons XInputGetState reports: all but the guide button and the
+// bit next to it, which is unused
+const BUTTONS: u16 = 0xF3FF;
+// Stick resolution XInputGetCapabilities reports: the top ten bits
+const THUMB_RESOLUTION: i16 = 0xFFC0u16 as i16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XInputGamepad {
+    pub buttons: u16,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub thumb_lx: i16,
+    pub thumb_ly: i16,
+    pub thumb_rx: i16,
+    pub thumb_ry: i16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XInputState {
+    pub packet_number: DWORD,
+    pub gamepad: XInputGamepad,
+}
+
+/// Motor speeds: the left, low-frequency motor, then the right one
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XInputVibration {
+    pub left_motor_speed: u16,
+    pub right_motor_speed: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XInputCapabilities {
+    pub kind: u8,
+    pub sub_type: u8,
+    pub flags: u16,
+    /// The buttons there are, and each axis's resolution as the bits set
+    pub gamepad: XInputGamepad,
+    pub vibration: XInputVibration,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XInputBatteryInformation {
+    pub battery_type: u8,
+    pub battery_level: u8,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+// The speeds each player's motors were last set to
+static VIBRATION: Mutex<[(u16, u16); MAX_PADS]> = Mutex::new([(0, 0); MAX_PADS]);
+
+// The slot a user index names, if it is one of the four
+fn slot(user_index: DWORD) -> Option<usize> {
+    let slot = user_index as usize;
+    (slot < MAX_PADS).then_some(slot)
+}
+
+/// XInputGetState - Read a pad's buttons, triggers and sticks
+#[no_mangle]
+pub extern "C" fn XInputGetState(user_index: DWORD, state: *mut XInputState) -> DWORD {
+    let Some(slot) = slot(user_index).filter(|_| !state.is_null()) else {
+        return ERROR_BAD_ARGUMENTS;
+    };
+    let Some((packet, current)) = gamepad::state(slot) else {
+        return ERROR_DEVICE_NOT_CONNECTED;
+    };
+    let mut out = XInputState { packet_number: packet, gamepad: XInputGamepad::default() };
+    if ENABLED.load(Ordering::Relaxed) {
+        out.gamepad = XInputGamepad {
+            buttons: current.buttons & BUTTONS,
+            left_trigger: current.left_trigger,
+            right_trigger: current.right_trigger,
+            thumb_lx: current.thumb_lx,
+            thumb_ly: current.thumb_ly,
+            thumb_rx: current.thumb_rx,
+            thumb_ry: current.thumb_ry,
+        };
+    }
+    unsafe { *state = out };
+    ERROR_SUCCESS
+}
+
+/// XInputSetState - Set a pad's motor speeds
+#[no_mangle]
+pub extern "C" fn XInputSetState(user_index: DWORD, vibration: *mut XInputVibration) -> DWORD {
+    let Some(slot) = slot(user_index).filter(|_| !vibration.is_null()) else {
+        return ERROR_BAD_ARGUMENTS;
+    };
+    if !gamepad::connected(slot) {
+        return ERROR_DEVICE_NOT_CONNECTED;
+    }
+    let speeds = unsafe { *vibration };
+    let speeds = (speeds.left_motor_speed, speeds.right_motor_speed);
+    VIBRATION.lock()[slot] = speeds;
+    if !ENABLED.load(Ordering::Relaxed) || !gamepad::can_rumble(slot) {
+        return ERROR_SUCCESS;
+    }
+    match gamepad::set_vibration(slot, speeds.0, speeds.1) {
+        Ok(()) => ERROR_SUCCESS,
+        Err(_) => ERROR_DEVICE_NOT_CONNECTED,
+    }
+}
+
+/// XInputGetCapabilities - What a pad has: every one is a gamepad with
+/// the full set of buttons and axes, and motors if its driver drives them
+#[no_mangle]
+pub extern "C" fn XInputGetCapabilities(
+    user_index: DWORD,
+    flags: DWORD,
+    capabilities: *mut XInputCapabilities,
+) -> DWORD {
+    let Some(slot) = slot(user_index).filter(|_| !capabilities.is_null()) else {
+        return ERROR_BAD_ARGUMENTS;
+    };
+    if flags & !XINPUT_FLAG_GAMEPAD != 0 {
+        return ERROR_BAD_ARGUMENTS;
+    }
+    if !gamepad::connected(slot) {
+        return ERROR_DEVICE_NOT_CONNECTED;
+    }
+    let motor = if gamepad::can_rumble(slot) { u16::MAX } else { 0 };
+    let out = XInputCapabilities {
+        kind: XINPUT_DEVTYPE_GAMEPAD,
+        sub_type: XINPUT_DEVSUBTYPE_GAMEPAD,
+        flags: 0,
+        gamepad: XInputGamepad {
+            buttons: BUTTONS,
+            left_trigger: u8::MAX,
+            right_trigger: u8::MAX,
+            thumb_lx: THUMB_RESOLUTION,
+            thumb_ly: THUMB_RESOLUTION,
+            thumb_rx: THUMB_RESOLUTION,
+            thumb_ry: THUMB_RESOLUTION,
+        },
+        vibration: XInputVibration { left_motor_speed: motor, right_motor_speed: motor },
+    };
+    unsafe { *capabilities = out };
+    ERROR_SUCCESS
+}
+
+/// XInputEnable - Turn reading and rumble on or off for every pad
+#[no_mangle]
+pub extern "C" fn XInputEnable(enable: BOOL) {
+    let enable = enable != 0;
+    if ENABLED.swap(enable, Ordering::Relaxed) == enable {
+        return;
+    }
+    let speeds = *VIBRATION.lock();
+    for (slot, (low, high)) in speeds.into_iter().enumerate() {
+        if !gamepad::can_rumble(slot) {
+            continue;
+        }
+        let (low, high) = if enable { (low, high) } else { (0, 0) };
+        // A pad unplugged meanwhile is not an error here
+        let _ = gamepad::set_vibration(slot, low, high);
+    }
+}
+
+/// XInputGetBatteryInformation - Every pad is wired, and has no headset
+#[no_mangle]
+pub extern "C" fn XInputGetBatteryInformation(
+    user_index: DWORD,
+    device_type: u8,
+    information: *mut XInputBatteryInformation,
+) -> DWORD {
+    let Some(slot) = slot(user_index).filter(|_| !information.is_null()) else {
+        return ERROR_BAD_ARGUMENTS;
+    };
+    if !gamepad::connected(slot) {
+        return ERROR_DEVICE_NOT_CONNECTED;
+    }
+    let out = match device_type {
+        BATTERY_DEVTYPE_GAMEPAD => {
+            XInputBatteryInformation { battery_type: BATTERY_TYPE_WIRED, battery_level: BATTERY_LEVEL_FULL }
+        }
+        BATTERY_DEVTYPE_HEADSET => {
+            XInputBatteryInformation { battery_type: BATTERY_TYPE_DISCONNECTED, battery_level: BATTERY_LEVEL_EMPTY }
+        }
+        _ => return ERROR_BAD_ARGUMENTS,
+    };
+    unsafe { *information = out };
+    ERROR_SUCCESS
+}
+
+/// XInputGetKeystroke - Keystrokes are not queued, so a connected pad has
+/// none
+#[no_mangle]
+pub extern "C" fn XInputGetKeystroke(user_index: DWORD, _reserved: DWORD, keystroke: *mut u8) -> DWORD {
+    // XUSER_INDEX_ANY asks every pad
+    const XUSER_INDEX_ANY: DWORD = 0xFF;
+    if keystroke.is_null() {
+        return ERROR_BAD_ARGUMENTS;
+    }
+    let connected = match user_index {
+        XUSER_INDEX_ANY => (0..MAX_PADS).any(gamepad::connected),
+        index => match slot(index) {
+            Some(slot) => gamepad::connected(slot),
+            None => return ERROR_BAD_ARGUMENTS,
+        },
+    };
+    if connected {
+        ERROR_EMPTY
+    } else {
+        ERROR_DEVICE_NOT_CONNECTED
+    }
+}
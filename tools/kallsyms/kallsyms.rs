@@ -0,0 +1,325 @@
+// kallsyms - embed the kernel symbol table into the linked kernel image
+//
+// Post-link step for kernel/src/debug/symbols.rs: reads the function symbols
+// from the kernel ELF's .symtab, demangles them, and writes them
+// prefix-compressed into the reserved .kallsyms section together with the
+// location of .eh_frame and .eh_frame_hdr for the unwinder. Section layout
+// and addresses do not change, so the image can be patched in place.
+//
+//   kallsyms <kernel-elf>
+//
+// Build with `rustc -O --edition 2021 kallsyms.rs`. Running it again on a
+// patched image rewrites the table.
+
+use std::env;
+use std::fs;
+use std::process;
+
+// Must match kernel/src/debug/symbols.rs
+const RESERVED_MAGIC: &[u8; 8] = b"KSYMRSVD";
+const TABLE_MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 48;
+const RESTART_INTERVAL: usize = 64;
+const MAX_NAME: usize = 255;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+struct Section {
+    name: String,
+    kind: u32,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: kallsyms <kernel-elf>");
+        process::exit(2);
+    }
+    if let Err(e) = run(&args[1]) {
+        eprintln!("kallsyms: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let mut image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let sections = parse_sections(&image)?;
+
+    let area = find(&sections, ".kallsyms")
+        .ok_or("no .kallsyms section; is this the kernel image?")?;
+    let start = area.offset as usize;
+    let capacity = area.size as usize;
+    let reserved = image.get(start..start + capacity).ok_or(".kallsyms lies outside the file")?;
+    if &reserved[..8] != RESERVED_MAGIC && &reserved[..8] != TABLE_MAGIC {
+        return Err(".kallsyms does not hold a reserved area".to_string());
+    }
+
+    let symbols = read_symbols(&image, &sections)?;
+    let eh_frame = find(&sections, ".eh_frame").map_or((0, 0), |s| (s.address, s.size));
+    let eh_frame_hdr = find(&sections, ".eh_frame_hdr").map_or((0, 0), |s| (s.address, s.size));
+    let table = build_table(&symbols, eh_frame, eh_frame_hdr);
+    if table.len() > capacity {
+        return Err(format!(
+            "symbol table needs {} bytes but .kallsyms holds {}; raise kallsyms::CAPACITY",
+            table.len(),
+            capacity
+        ));
+    }
+
+    let area = &mut image[start..start + capacity];
+    area.fill(0);
+    area[..table.len()].copy_from_slice(&table);
+    fs::write(path, &image).map_err(|e| format!("{}: {}", path, e))?;
+
+    println!(
+        "kallsyms: {} symbols in {} of {} bytes{}",
+        symbols.len(),
+        table.len(),
+        capacity,
+        if eh_frame.0 == 0 { ", no .eh_frame" } else { "" }
+    );
+    Ok(())
+}
+
+fn find<'a>(sections: &'a [Section], name: &str) -> Option<&'a Section> {
+    sections.iter().find(|s| s.name == name)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF".to_string())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, String> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "truncated ELF".to_string())
+}
+
+fn c_string(data: &[u8], offset: usize) -> String {
+    let bytes = data.get(offset..).unwrap_or(&[]);
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn parse_sections(image: &[u8]) -> Result<Vec<Section>, String> {
+    if image.len() < 64 || &image[..4] != b"\x7fELF" {
+        return Err("not an ELF file".to_string());
+    }
+    if image[4] != 2 || image[5] != 1 {
+        return Err("not a little-endian ELF64 file".to_string());
+    }
+
+    let table = u64_at(image, 0x28)? as usize;
+    let entry_size = u16_at(image, 0x3a)? as usize;
+    let count = u16_at(image, 0x3c)? as usize;
+    let names_index = u16_at(image, 0x3e)? as usize;
+
+    let mut sections = Vec::with_capacity(count);
+    for i in 0..count {
+        let header = table + i * entry_size;
+        sections.push((
+            u32_at(image, header)?,
+            Section {
+                name: String::new(),
+                kind: u32_at(image, header + 4)?,
+                address: u64_at(image, header + 16)?,
+                offset: u64_at(image, header + 24)?,
+                size: u64_at(image, header + 32)?,
+                link: u32_at(image, header + 40)?,
+            },
+        ));
+    }
+
+    let names = sections.get(names_index).map(|(_, s)| s.offset as usize).ok_or("bad section name table")?;
+    Ok(sections
+        .into_iter()
+        .map(|(name, mut section)| {
+            section.name = c_string(image, names + name as usize);
+            section
+        })
+        .collect())
+}
+
+fn read_symbols(image: &[u8], sections: &[Section]) -> Result<Vec<Symbol>, String> {
+    let symtab = sections
+        .iter()
+        .find(|s| s.kind == SHT_SYMTAB)
+        .ok_or("no .symtab; the kernel must not be stripped")?;
+    let strtab = sections.get(symtab.link as usize).ok_or("bad .symtab string table")?;
+
+    let mut symbols = Vec::new();
+    for i in 0..(symtab.size / 24) as usize {
+        let entry = symtab.offset as usize + i * 24;
+        let name = u32_at(image, entry)?;
+        let info = image[entry + 4];
+        let section = u16_at(image, entry + 6)?;
+        let address = u64_at(image, entry + 8)?;
+        let size = u64_at(image, entry + 16)?;
+        if info & 0xf != STT_FUNC || section == SHN_UNDEF || address == 0 {
+            continue;
+        }
+        symbols.push(Symbol {
+            address,
+            size,
+            name: demangle(&c_string(image, strtab.offset as usize + name as usize)),
+        });
+    }
+
+    // One name per address, preferring the symbol with a size
+    symbols.sort_by(|a, b| a.address.cmp(&b.address).then(b.size.cmp(&a.size)));
+    symbols.dedup_by_key(|s| s.address);
+
+    // Fill in missing sizes up to the next symbol
+    for i in 0..symbols.len() {
+        if symbols[i].size == 0 {
+            if let Some(next) = symbols.get(i + 1).map(|s| s.address) {
+                symbols[i].size = next - symbols[i].address;
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+// Legacy Rust mangling: _ZN <length><identifier>... 17h<hash> E
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return name.to_string();
+    };
+
+    let mut parts = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Ok(length) = rest[..digits].parse::<usize>() else {
+            return name.to_string();
+        };
+        let Some(part) = rest.get(digits..digits + length) else {
+            return name.to_string();
+        };
+        parts.push(part);
+        rest = &rest[digits + length..];
+    }
+
+    if let Some(last) = parts.last() {
+        if last.len() == 17 && last.starts_with('h') && last[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+
+    parts.iter().map(|part| unescape(part)).collect::<Vec<_>>().join("::")
+}
+
+fn unescape(part: &str) -> String {
+    let part = part.strip_prefix("_$").map_or(part.to_string(), |p| format!("${}", p));
+    let mut out = String::new();
+    let mut rest = part.as_str();
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = tail;
+        } else if rest.starts_with('$') {
+            let Some(end) = rest[1..].find('$') else {
+                out.push_str(rest);
+                break;
+            };
+            let code = &rest[1..end + 1];
+            match code {
+                "SP" => out.push('@'),
+                "BP" => out.push('*'),
+                "RF" => out.push('&'),
+                "LT" => out.push('<'),
+                "GT" => out.push('>'),
+                "LP" => out.push('('),
+                "RP" => out.push(')'),
+                "C" => out.push(','),
+                _ => match code.strip_prefix('u').and_then(|hex| u32::from_str_radix(hex, 16).ok()) {
+                    Some(c) => out.push(char::from_u32(c).unwrap_or('?')),
+                    None => out.push_str(&rest[..end + 2]),
+                },
+            }
+            rest = &rest[end + 2..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+fn push_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn build_table(symbols: &[Symbol], eh_frame: (u64, u64), eh_frame_hdr: (u64, u64)) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(TABLE_MAGIC);
+    table.extend_from_slice(&[0; 4]); // length, filled in below
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&eh_frame.0.to_le_bytes());
+    table.extend_from_slice(&eh_frame.1.to_le_bytes());
+    table.extend_from_slice(&eh_frame_hdr.0.to_le_bytes());
+    table.extend_from_slice(&eh_frame_hdr.1.to_le_bytes());
+    assert_eq!(table.len(), HEADER_SIZE);
+
+    let mut previous_address = 0;
+    let mut previous_name: &[u8] = &[];
+    for (i, symbol) in symbols.iter().enumerate() {
+        let mut name = symbol.name.as_bytes();
+        if name.len() > MAX_NAME {
+            let mut end = MAX_NAME;
+            while !symbol.name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name = &name[..end];
+        }
+
+        // Restart entries are self-contained so the kernel can index them
+        let restart = i % RESTART_INTERVAL == 0;
+        let shared = if restart {
+            0
+        } else {
+            name.iter().zip(previous_name).take_while(|(a, b)| a == b).count()
+        };
+        push_uleb128(&mut table, symbol.address - if restart { 0 } else { previous_address });
+        push_uleb128(&mut table, symbol.size);
+        table.push(shared as u8);
+        push_uleb128(&mut table, (name.len() - shared) as u64);
+        table.extend_from_slice(&name[shared..]);
+
+        previous_address = symbol.address;
+        previous_name = name;
+    }
+
+    let length = table.len() as u32;
+    table[8..12].copy_from_slice(&length.to_le_bytes());
+    table
+}
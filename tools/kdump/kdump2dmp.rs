@@ -0,0 +1,696 @@
+// kdump2dmp - receive kernel crash dumps and convert them for WinDbg
+//
+// Host-side companion of kernel/src/debug/kdump.rs:
+//
+//   kdump2dmp receive <serial-device> <dump.kdump>   receive over serial,
+//                                                     resuming a partial dump
+//   kdump2dmp extract <capture> <dump.kdump>         recover a dump from a raw
+//                                                     capture of the port
+//   kdump2dmp info <dump.kdump>                      summarize a dump
+//   kdump2dmp convert <dump.kdump> <crash.dmp>       write a minidump
+//
+// Build with `rustc -O kdump2dmp.rs`. Configure the serial device first, e.g.
+// `stty -F /dev/ttyUSB0 115200 raw -echo`.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process;
+
+const DUMP_SIGNATURE: &[u8; 8] = b"KDUMPV02";
+const TRAILER_SIGNATURE: &[u8; 8] = b"KDUMPEND";
+const HEADER_SIZE: usize = 64;
+const DIRECTORY_ENTRY_SIZE: usize = 24;
+const TRAILER_SIZE: usize = 16;
+const CPU_RECORD_SIZE: usize = 200;
+const MEMORY_RECORD_HEADER_SIZE: usize = 32;
+
+const SECTION_PANIC_MESSAGE: u32 = 1;
+const SECTION_CPU_CONTEXTS: u32 = 2;
+const SECTION_KERNEL_LOG: u32 = 3;
+const SECTION_MODULES: u32 = 4;
+const SECTION_MEMORY: u32 = 5;
+
+const FRAME_MAGIC: &[u8; 3] = b"KDF";
+const FRAME_HELLO: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_END: u8 = 3;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const RESUME: u8 = b'R';
+const MAX_PAYLOAD: usize = 1024;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("receive") if args.len() == 4 => receive(&args[2], &args[3]),
+        Some("extract") if args.len() == 4 => extract(&args[2], &args[3]),
+        Some("info") if args.len() == 3 => load(&args[2]).map(|dump| print_info(&dump)),
+        Some("convert") if args.len() == 4 => {
+            load(&args[2]).and_then(|dump| fs::write(&args[3], build_minidump(&dump)?).map_err(|e| e.to_string()))
+        }
+        _ => Err("usage: kdump2dmp receive <serial-device> <dump.kdump>\n       \
+                  kdump2dmp extract <capture> <dump.kdump>\n       \
+                  kdump2dmp info <dump.kdump>\n       \
+                  kdump2dmp convert <dump.kdump> <crash.dmp>"
+            .to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("kdump2dmp: {}", e);
+        process::exit(1);
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// Serial protocol
+
+struct Frame {
+    kind: u8,
+    offset: u64,
+    payload: Vec<u8>,
+}
+
+/// Reads frames from a byte stream, resynchronizing on the frame magic
+struct FrameReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> FrameReader<R> {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        match self.input.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn exact(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        for slot in buffer.iter_mut() {
+            match self.byte()? {
+                Some(byte) => *slot = byte,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Next frame; Ok(Some(Err(()))) for a frame that failed its CRC, Ok(None)
+    /// at end of input
+    fn next(&mut self) -> io::Result<Option<Result<Frame, ()>>> {
+        let mut matched = 0;
+        while matched < FRAME_MAGIC.len() {
+            let Some(byte) = self.byte()? else {
+                return Ok(None);
+            };
+            matched = if byte == FRAME_MAGIC[matched] {
+                matched + 1
+            } else if byte == FRAME_MAGIC[0] {
+                1
+            } else {
+                0
+            };
+        }
+
+        let mut head = [0u8; 15];
+        if !self.exact(&mut head)? {
+            return Ok(None);
+        }
+        let length = u16_at(&head, 13) as usize;
+        if length > MAX_PAYLOAD {
+            return Ok(Some(Err(())));
+        }
+        let mut body = vec![0u8; length + 4];
+        if !self.exact(&mut body)? {
+            return Ok(None);
+        }
+
+        let mut covered = head.to_vec();
+        covered.extend_from_slice(&body[..length]);
+        if crc32(&covered) != u32_at(&body, length) {
+            return Ok(Some(Err(())));
+        }
+        body.truncate(length);
+        Ok(Some(Ok(Frame { kind: head[0], offset: u64_at(&head, 5), payload: body })))
+    }
+}
+
+/// A dump being received: the data so far, kept in `<output>.part` behind
+/// the dump id so that a later session can resume it
+struct PartialDump {
+    path: String,
+    file: File,
+    dump_id: u64,
+    total_size: u64,
+    received: u64,
+}
+
+impl PartialDump {
+    fn open(output: &str, dump_id: u64, total_size: u64) -> Result<Self, String> {
+        let path = format!("{}.part", output);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut id = [0u8; 8];
+        let resumable = file.read_exact(&mut id).is_ok() && u64::from_le_bytes(id) == dump_id;
+        if !resumable {
+            file.set_len(0).map_err(|e| e.to_string())?;
+            file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+            file.write_all(&dump_id.to_le_bytes()).map_err(|e| e.to_string())?;
+        }
+        let received = file.metadata().map_err(|e| e.to_string())?.len() - 8;
+        Ok(Self { path, file, dump_id, total_size, received: received.min(total_size) })
+    }
+
+    fn append(&mut self, offset: u64, data: &[u8]) -> Result<bool, String> {
+        if offset > self.received {
+            return Ok(false);
+        }
+        let skip = (self.received - offset) as usize;
+        if skip < data.len() {
+            self.file.seek(SeekFrom::Start(8 + self.received)).map_err(|e| e.to_string())?;
+            self.file.write_all(&data[skip..]).map_err(|e| e.to_string())?;
+            self.received += (data.len() - skip) as u64;
+        }
+        Ok(true)
+    }
+
+    // Check the trailer CRC and move the dump into place
+    fn complete(mut self, output: &str) -> Result<(), String> {
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(8)).map_err(|e| e.to_string())?;
+        self.file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        Dump::parse(data.clone())?;
+        fs::write(output, &data).map_err(|e| format!("{}: {}", output, e))?;
+        let _ = fs::remove_file(&self.path);
+        Ok(())
+    }
+}
+
+fn receive(device: &str, output: &str) -> Result<(), String> {
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(device)
+        .map_err(|e| format!("{}: {}", device, e))?;
+    let mut reply = port.try_clone().map_err(|e| e.to_string())?;
+    let mut frames = FrameReader { input: port };
+    let mut dump: Option<PartialDump> = None;
+
+    eprintln!("Waiting for a dump on {}", device);
+    while let Some(frame) = frames.next().map_err(|e| e.to_string())? {
+        let Ok(frame) = frame else {
+            reply.write_all(&[NAK]).map_err(|e| e.to_string())?;
+            continue;
+        };
+        match frame.kind {
+            FRAME_HELLO if frame.payload.len() == 16 => {
+                let dump_id = u64_at(&frame.payload, 0);
+                let total_size = u64_at(&frame.payload, 8);
+                let partial = match dump.take() {
+                    Some(partial) if partial.dump_id == dump_id => partial,
+                    _ => PartialDump::open(output, dump_id, total_size)?,
+                };
+                eprintln!("Dump {}: {} bytes, resuming at {}", dump_id, total_size, partial.received);
+                let mut answer = vec![RESUME];
+                answer.extend_from_slice(&partial.received.to_le_bytes());
+                reply.write_all(&answer).map_err(|e| e.to_string())?;
+                dump = Some(partial);
+            }
+            FRAME_DATA => {
+                let accepted = match dump.as_mut() {
+                    Some(partial) => partial.append(frame.offset, &frame.payload)?,
+                    None => false,
+                };
+                reply.write_all(&[if accepted { ACK } else { NAK }]).map_err(|e| e.to_string())?;
+            }
+            FRAME_END => {
+                let Some(partial) = dump.take() else {
+                    reply.write_all(&[NAK]).map_err(|e| e.to_string())?;
+                    continue;
+                };
+                if partial.received != partial.total_size {
+                    reply.write_all(&[NAK]).map_err(|e| e.to_string())?;
+                    dump = Some(partial);
+                    continue;
+                }
+                reply.write_all(&[ACK]).map_err(|e| e.to_string())?;
+                partial.complete(output)?;
+                eprintln!("Dump written to {}", output);
+                return Ok(());
+            }
+            _ => reply.write_all(&[NAK]).map_err(|e| e.to_string())?,
+        }
+    }
+    Err("Serial device closed before the dump completed".to_string())
+}
+
+fn extract(capture: &str, output: &str) -> Result<(), String> {
+    let input = File::open(capture).map_err(|e| format!("{}: {}", capture, e))?;
+    let mut frames = FrameReader { input: io::BufReader::new(input) };
+    let mut data: Vec<u8> = Vec::new();
+    let mut total_size = None;
+
+    while let Some(frame) = frames.next().map_err(|e| e.to_string())? {
+        let Ok(frame) = frame else { continue };
+        match frame.kind {
+            FRAME_HELLO if frame.payload.len() == 16 => total_size = Some(u64_at(&frame.payload, 8)),
+            FRAME_DATA if frame.offset as usize <= data.len() => {
+                let skip = data.len() - frame.offset as usize;
+                if skip < frame.payload.len() {
+                    data.extend_from_slice(&frame.payload[skip..]);
+                }
+            }
+            FRAME_DATA => return Err(format!("Capture is missing data at offset {}", data.len())),
+            FRAME_END => break,
+            _ => {}
+        }
+    }
+    if total_size != Some(data.len() as u64) {
+        return Err(format!("Capture holds {} bytes of a {:?} byte dump", data.len(), total_size));
+    }
+    Dump::parse(data.clone())?;
+    fs::write(output, &data).map_err(|e| format!("{}: {}", output, e))
+}
+
+// Dump parsing
+
+struct CpuContext {
+    cpu_id: u32,
+    apic_id: u32,
+    // rax rbx rcx rdx rsi rdi rbp rsp r8-r15 rip rflags cr0 cr2 cr3 cr4
+    registers: [u64; 22],
+    // cs ds es fs gs ss
+    selectors: [u16; 6],
+}
+
+impl CpuContext {
+    fn rsp(&self) -> u64 {
+        self.registers[7]
+    }
+
+    fn rip(&self) -> u64 {
+        self.registers[16]
+    }
+}
+
+struct Module {
+    name: String,
+    base: u64,
+    size: u64,
+}
+
+struct MemoryRange {
+    address: u64,
+    data: Vec<u8>,
+}
+
+struct Dump {
+    dump_id: u64,
+    timestamp: u64,
+    dump_type: u32,
+    crashing_cpu: u32,
+    panic_message: String,
+    cpus: Vec<CpuContext>,
+    kernel_log: Vec<u8>,
+    modules: Vec<Module>,
+    memory: Vec<MemoryRange>,
+}
+
+fn load(path: &str) -> Result<Dump, String> {
+    Dump::parse(fs::read(path).map_err(|e| format!("{}: {}", path, e))?)
+}
+
+impl Dump {
+    fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < HEADER_SIZE + TRAILER_SIZE || &data[0..8] != DUMP_SIGNATURE {
+            return Err("Not a kernel crash dump".to_string());
+        }
+        let total_size = u64_at(&data, 32) as usize;
+        if data.len() < total_size {
+            return Err(format!("Dump is truncated: {} of {} bytes", data.len(), total_size));
+        }
+        let trailer = &data[total_size - TRAILER_SIZE..total_size];
+        if &trailer[0..8] != TRAILER_SIGNATURE {
+            return Err("Dump has no trailer; it was not written completely".to_string());
+        }
+        if crc32(&data[..total_size - TRAILER_SIZE]) != u32_at(trailer, 8) {
+            return Err("Dump checksum mismatch".to_string());
+        }
+
+        let mut dump = Dump {
+            dump_id: u64_at(&data, 16),
+            timestamp: u64_at(&data, 24),
+            dump_type: u32_at(&data, 52),
+            crashing_cpu: u32_at(&data, 48),
+            panic_message: String::new(),
+            cpus: Vec::new(),
+            kernel_log: Vec::new(),
+            modules: Vec::new(),
+            memory: Vec::new(),
+        };
+
+        let section_count = u32_at(&data, 40) as usize;
+        for i in 0..section_count {
+            let entry = HEADER_SIZE + i * DIRECTORY_ENTRY_SIZE;
+            let kind = u32_at(&data, entry);
+            let count = u32_at(&data, entry + 4) as usize;
+            let offset = u64_at(&data, entry + 8) as usize;
+            let size = u64_at(&data, entry + 16) as usize;
+            let section = data
+                .get(offset..offset + size)
+                .ok_or_else(|| format!("Section {} lies outside the dump", kind))?;
+            match kind {
+                SECTION_PANIC_MESSAGE => dump.panic_message = String::from_utf8_lossy(section).into_owned(),
+                SECTION_CPU_CONTEXTS => {
+                    for record in section.chunks_exact(CPU_RECORD_SIZE).take(count) {
+                        let mut registers = [0u64; 22];
+                        for (j, register) in registers.iter_mut().enumerate() {
+                            *register = u64_at(record, 8 + j * 8);
+                        }
+                        let mut selectors = [0u16; 6];
+                        for (j, selector) in selectors.iter_mut().enumerate() {
+                            *selector = u16_at(record, 184 + j * 2);
+                        }
+                        dump.cpus.push(CpuContext {
+                            cpu_id: u32_at(record, 0),
+                            apic_id: u32_at(record, 4),
+                            registers,
+                            selectors,
+                        });
+                    }
+                }
+                SECTION_KERNEL_LOG => dump.kernel_log = section.to_vec(),
+                SECTION_MODULES => {
+                    let mut at = 0;
+                    for _ in 0..count {
+                        let name_len = u32_at(section, at + 16) as usize;
+                        let name = &section[at + 20..at + 20 + name_len];
+                        dump.modules.push(Module {
+                            name: String::from_utf8_lossy(name).into_owned(),
+                            base: u64_at(section, at),
+                            size: u64_at(section, at + 8),
+                        });
+                        at += 20 + name_len;
+                    }
+                }
+                SECTION_MEMORY => {
+                    let mut at = 0;
+                    for _ in 0..count {
+                        let address = u64_at(section, at);
+                        let size = u64_at(section, at + 16) as usize;
+                        let start = at + MEMORY_RECORD_HEADER_SIZE;
+                        dump.memory.push(MemoryRange { address, data: section[start..start + size].to_vec() });
+                        at = start + size;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(dump)
+    }
+}
+
+fn print_info(dump: &Dump) {
+    let dump_type = match dump.dump_type {
+        1 => "mini",
+        2 => "kernel",
+        3 => "full",
+        4 => "live",
+        _ => "unknown",
+    };
+    println!("Dump {} ({} dump), timestamp {}", dump.dump_id, dump_type, dump.timestamp);
+    println!("Panic: {}", dump.panic_message);
+    println!("Crashing CPU: {}", dump.crashing_cpu);
+    for cpu in &dump.cpus {
+        let r = &cpu.registers;
+        println!("CPU {} (APIC {}):", cpu.cpu_id, cpu.apic_id);
+        println!("  rax={:016x} rbx={:016x} rcx={:016x} rdx={:016x}", r[0], r[1], r[2], r[3]);
+        println!("  rsi={:016x} rdi={:016x} rbp={:016x} rsp={:016x}", r[4], r[5], r[6], r[7]);
+        println!("  r8 ={:016x} r9 ={:016x} r10={:016x} r11={:016x}", r[8], r[9], r[10], r[11]);
+        println!("  r12={:016x} r13={:016x} r14={:016x} r15={:016x}", r[12], r[13], r[14], r[15]);
+        println!("  rip={:016x} rflags={:08x} cr2={:016x} cr3={:016x}", r[16], r[17], r[19], r[20]);
+        println!("  cs={:04x} ss={:04x}", cpu.selectors[0], cpu.selectors[5]);
+    }
+    println!("Modules:");
+    for module in &dump.modules {
+        println!("  {:016x} {:10x} {}", module.base, module.size, module.name);
+    }
+    println!("Memory:");
+    for range in &dump.memory {
+        println!("  {:016x} {:10x}", range.address, range.data.len());
+    }
+    let log = String::from_utf8_lossy(&dump.kernel_log);
+    let tail: Vec<&str> = log.lines().rev().take(20).collect();
+    println!("Kernel log (last {} lines):", tail.len());
+    for line in tail.iter().rev() {
+        println!("  {}", line);
+    }
+}
+
+// Minidump output (see MINIDUMP_* in the Windows SDK's minidumpapiset.h)
+
+const MINIDUMP_SIGNATURE: u32 = 0x504d_444d; // "MDMP"
+const MINIDUMP_VERSION: u32 = 0xa793;
+const THREAD_LIST_STREAM: u32 = 3;
+const MODULE_LIST_STREAM: u32 = 4;
+const MEMORY_LIST_STREAM: u32 = 5;
+const EXCEPTION_STREAM: u32 = 6;
+const SYSTEM_INFO_STREAM: u32 = 7;
+const COMMENT_STREAM_A: u32 = 10;
+
+const CONTEXT_SIZE: usize = 0x4d0;
+// CONTEXT_AMD64 | CONTEXT_CONTROL | CONTEXT_INTEGER | CONTEXT_SEGMENTS
+const CONTEXT_FLAGS: u32 = 0x0010_0007;
+const PROCESSOR_ARCHITECTURE_AMD64: u16 = 9;
+const VER_PLATFORM_WIN32_NT: u32 = 2;
+// Application-defined exception code reported for a kernel panic
+const KERNEL_PANIC_EXCEPTION: u32 = 0xe000_0001;
+
+struct MinidumpWriter {
+    out: Vec<u8>,
+}
+
+impl MinidumpWriter {
+    fn rva(&self) -> u32 {
+        self.out.len() as u32
+    }
+
+    fn align(&mut self, to: usize) {
+        while self.out.len() % to != 0 {
+            self.out.push(0);
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> u32 {
+        self.align(4);
+        let rva = self.rva();
+        self.out.extend_from_slice(bytes);
+        rva
+    }
+
+    // MINIDUMP_STRING: byte length, UTF-16LE, terminating NUL
+    fn put_string(&mut self, text: &str) -> u32 {
+        let utf16: Vec<u16> = text.encode_utf16().collect();
+        let mut bytes = ((utf16.len() * 2) as u32).to_le_bytes().to_vec();
+        for unit in utf16.iter().chain(std::iter::once(&0)) {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        self.put(&bytes)
+    }
+
+    fn patch_u32(&mut self, at: usize, value: u32) {
+        self.out[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+struct Builder(Vec<u8>);
+
+impl Builder {
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn zeros(mut self, count: usize) -> Self {
+        self.0.resize(self.0.len() + count, 0);
+        self
+    }
+}
+
+fn amd64_context(cpu: &CpuContext) -> Vec<u8> {
+    let mut context = vec![0u8; CONTEXT_SIZE];
+    let mut set = |offset: usize, bytes: &[u8]| context[offset..offset + bytes.len()].copy_from_slice(bytes);
+    let r = &cpu.registers;
+    set(0x30, &CONTEXT_FLAGS.to_le_bytes());
+    set(0x34, &0x1f80u32.to_le_bytes()); // MxCsr
+    for (i, selector) in cpu.selectors.iter().enumerate() {
+        set(0x38 + i * 2, &selector.to_le_bytes());
+    }
+    set(0x44, &(r[17] as u32).to_le_bytes()); // EFlags
+    // Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi, R8-R15, Rip
+    let order = [0, 2, 3, 1, 7, 6, 4, 5, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    for (i, &register) in order.iter().enumerate() {
+        set(0x78 + i * 8, &r[register].to_le_bytes());
+    }
+    context
+}
+
+fn build_minidump(dump: &Dump) -> Result<Vec<u8>, String> {
+    const STREAM_COUNT: usize = 6;
+    let mut w = MinidumpWriter { out: Vec::new() };
+
+    // Header and stream directory, filled in at the end
+    w.put(&[0u8; 32]);
+    let directory = w.put(&vec![0u8; STREAM_COUNT * 12]) as usize;
+    let mut streams: Vec<(u32, u32, u32)> = Vec::new();
+
+    // Memory contents, shared by the memory list and the thread stacks
+    let mut memory = Vec::new();
+    for range in &dump.memory {
+        let size = u32::try_from(range.data.len()).map_err(|_| "Memory range over 4 GiB".to_string())?;
+        let rva = w.put(&range.data);
+        memory.push((range.address, size, rva));
+    }
+    let mut list = Builder(Vec::new()).u32(memory.len() as u32);
+    for &(address, size, rva) in &memory {
+        list = list.u64(address).u32(size).u32(rva);
+    }
+    let list = list.0;
+    streams.push((MEMORY_LIST_STREAM, list.len() as u32, w.put(&list)));
+
+    // One thread per CPU
+    let mut contexts = Vec::new();
+    for cpu in &dump.cpus {
+        let context = amd64_context(cpu);
+        contexts.push((cpu.cpu_id, w.put(&context)));
+    }
+    let mut threads = Builder(Vec::new()).u32(dump.cpus.len() as u32);
+    for (cpu, &(_, context_rva)) in dump.cpus.iter().zip(&contexts) {
+        let stack = memory
+            .iter()
+            .find(|&&(address, size, _)| cpu.rsp() >= address && cpu.rsp() < address + size as u64)
+            .copied()
+            .unwrap_or((0, 0, 0));
+        threads = threads
+            .u32(cpu.cpu_id)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u64(0)
+            .u64(stack.0)
+            .u32(stack.1)
+            .u32(stack.2)
+            .u32(CONTEXT_SIZE as u32)
+            .u32(context_rva);
+    }
+    let threads = threads.0;
+    streams.push((THREAD_LIST_STREAM, threads.len() as u32, w.put(&threads)));
+
+    // Modules
+    let names: Vec<u32> = dump.modules.iter().map(|module| w.put_string(&module.name)).collect();
+    let mut modules = Builder(Vec::new()).u32(dump.modules.len() as u32);
+    for (module, &name) in dump.modules.iter().zip(&names) {
+        let size = u32::try_from(module.size).unwrap_or(u32::MAX);
+        modules = modules.u64(module.base).u32(size).u32(0).u32(0).u32(name).zeros(52 + 8 + 8 + 8 + 8);
+    }
+    let modules = modules.0;
+    streams.push((MODULE_LIST_STREAM, modules.len() as u32, w.put(&modules)));
+
+    // The panic, as an exception on the crashing CPU's thread
+    if let Some(&(cpu_id, context_rva)) = contexts.iter().find(|(id, _)| *id == dump.crashing_cpu) {
+        let rip = dump.cpus.iter().find(|cpu| cpu.cpu_id == cpu_id).map_or(0, CpuContext::rip);
+        let exception = Builder(Vec::new())
+            .u32(cpu_id)
+            .u32(0)
+            .u32(KERNEL_PANIC_EXCEPTION)
+            .u32(0)
+            .u64(0)
+            .u64(rip)
+            .u32(0)
+            .u32(0)
+            .zeros(15 * 8)
+            .u32(CONTEXT_SIZE as u32)
+            .u32(context_rva)
+            .0;
+        streams.push((EXCEPTION_STREAM, exception.len() as u32, w.put(&exception)));
+    }
+
+    // System information: the kernel reports itself as NT 5.2
+    let csd_version = w.put_string("");
+    let system = Builder(Vec::new())
+        .u16(PROCESSOR_ARCHITECTURE_AMD64)
+        .u16(6)
+        .u16(0)
+        .u16(dump.cpus.len().min(255) as u16 | 1 << 8) // NumberOfProcessors, ProductType = VER_NT_WORKSTATION
+        .u32(5)
+        .u32(2)
+        .u32(3790)
+        .u32(VER_PLATFORM_WIN32_NT)
+        .u32(csd_version)
+        .u16(0)
+        .u16(0)
+        .zeros(24)
+        .0;
+    streams.push((SYSTEM_INFO_STREAM, system.len() as u32, w.put(&system)));
+
+    // Panic message and kernel log, readable with .dumpdebug
+    let mut comment = format!("Kernel panic: {}\n\n", dump.panic_message).into_bytes();
+    comment.extend_from_slice(&dump.kernel_log);
+    comment.push(0);
+    streams.push((COMMENT_STREAM_A, comment.len() as u32, w.put(&comment)));
+
+    for (i, &(kind, size, rva)) in streams.iter().enumerate() {
+        let entry = directory + i * 12;
+        w.patch_u32(entry, kind);
+        w.patch_u32(entry + 4, size);
+        w.patch_u32(entry + 8, rva);
+    }
+    let header = Builder(Vec::new())
+        .u32(MINIDUMP_SIGNATURE)
+        .u32(MINIDUMP_VERSION)
+        .u32(streams.len() as u32)
+        .u32(directory as u32)
+        .u32(0)
+        .u32(dump.timestamp as u32)
+        .u64(0)
+        .0;
+    w.out[..32].copy_from_slice(&header);
+    Ok(w.out)
+}
@@ -0,0 +1,179 @@
+//! The event loop
+//!
+//! An [`App`] owns a window and the widgets in it. [`App::run`] takes the
+//! window's events until the app quits, hands each to the widgets, and
+//! draws again whatever changed. The program hears of what happened
+//! through the handler it passes, as a [`Signal`].
+//!
+//! A click gives the focus to the widget under the pointer, and Tab moves
+//! it on to the next one; what is typed goes to the focused widget.
+
+use crate::canvas::{Color, FACE};
+use crate::sys::{self, Event, BUTTON_LEFT};
+use crate::widget::{Response, Widget};
+use crate::window::Window;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::time::Duration;
+use libsys::{thread, Result};
+
+// How long the loop sleeps when there is nothing to do. The kernel does
+// not block on events, so this is the latency of a click.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A widget added to an [`App`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WidgetId(usize);
+
+/// What the handler passed to [`App::run`] hears of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// A widget was used
+    Activated(WidgetId),
+    /// The tick interval went by
+    Tick,
+    /// The close button was clicked; the app quits after the handler
+    Close,
+}
+
+pub struct App {
+    window: Window,
+    widgets: Vec<Box<dyn Widget>>,
+    focus: Option<usize>,
+    background: Color,
+    dirty: bool,
+    running: bool,
+}
+
+impl App {
+    pub fn new(window: Window) -> Self {
+        Self { window, widgets: Vec::new(), focus: None, background: FACE, dirty: true, running: true }
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    pub fn set_background(&mut self, color: Color) {
+        self.background = color;
+        self.dirty = true;
+    }
+
+    pub fn add<W: Widget>(&mut self, widget: W) -> WidgetId {
+        self.widgets.push(Box::new(widget));
+        self.dirty = true;
+        WidgetId(self.widgets.len() - 1)
+    }
+
+    /// The widget `id`, if it is a `W`
+    pub fn get<W: Widget>(&self, id: WidgetId) -> Option<&W> {
+        let widget: &dyn Any = self.widgets.get(id.0)?.as_ref();
+        widget.downcast_ref()
+    }
+
+    /// The widget `id`, if it is a `W`, to change; it is drawn again
+    pub fn get_mut<W: Widget>(&mut self, id: WidgetId) -> Option<&mut W> {
+        self.dirty = true;
+        let widget: &mut dyn Any = self.widgets.get_mut(id.0)?.as_mut();
+        widget.downcast_mut()
+    }
+
+    pub fn focus(&mut self, id: WidgetId) {
+        if self.widgets.get(id.0).is_some_and(|widget| widget.focusable()) {
+            self.focus = Some(id.0);
+            self.dirty = true;
+        }
+    }
+
+    /// Leave the event loop once the event being handled is done
+    pub fn quit(&mut self) {
+        self.running = false;
+    }
+
+    /// Draw every widget and put the window on the screen
+    pub fn redraw(&mut self) -> Result<()> {
+        let mut canvas = self.window.canvas();
+        canvas.clear(self.background);
+        for (index, widget) in self.widgets.iter().enumerate() {
+            widget.draw(&mut canvas, self.focus == Some(index));
+        }
+        self.dirty = false;
+        self.window.present(None)
+    }
+
+    /// Handle events until the app quits, calling `handler` with what
+    /// happened, and with [`Signal::Tick`] roughly every `tick` unless it
+    /// is zero
+    pub fn run(&mut self, tick: Duration, mut handler: impl FnMut(&mut App, Signal)) -> Result<()> {
+        let mut waited = Duration::ZERO;
+        while self.running {
+            while let Some(event) = self.window.next_event()? {
+                self.dispatch(&event, &mut handler);
+                if !self.running {
+                    return Ok(());
+                }
+            }
+            if self.dirty {
+                self.redraw()?;
+            }
+            thread::sleep(POLL_INTERVAL);
+            waited += POLL_INTERVAL;
+            if !tick.is_zero() && waited >= tick {
+                waited = Duration::ZERO;
+                handler(self, Signal::Tick);
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, event: &Event, handler: &mut impl FnMut(&mut App, Signal)) {
+        match event.kind {
+            sys::EVENT_CLOSE => {
+                handler(self, Signal::Close);
+                self.quit();
+                return;
+            }
+            sys::EVENT_BUTTON_DOWN if event.code == BUTTON_LEFT => {
+                let under = self.widgets.iter().position(|widget| widget.focusable() && widget.rect().contains(event.x, event.y));
+                if under != self.focus {
+                    self.focus = under;
+                    self.dirty = true;
+                }
+            }
+            sys::EVENT_CHAR if char::from_u32(event.code) == Some('\t') => {
+                self.focus_next();
+                return;
+            }
+            sys::EVENT_FOCUS_IN | sys::EVENT_FOCUS_OUT => return,
+            _ => {}
+        }
+
+        let mut activated = Vec::new();
+        for (index, widget) in self.widgets.iter_mut().enumerate() {
+            let focused = self.focus == Some(index);
+            // Only the focused widget hears typing
+            if event.kind == sys::EVENT_CHAR && !focused {
+                continue;
+            }
+            match widget.handle(event, focused) {
+                Response::Ignored => {}
+                Response::Redraw => self.dirty = true,
+                Response::Activated => {
+                    self.dirty = true;
+                    activated.push(WidgetId(index));
+                }
+            }
+        }
+        for id in activated {
+            handler(self, Signal::Activated(id));
+        }
+    }
+
+    fn focus_next(&mut self) {
+        let count = self.widgets.len();
+        let start = self.focus.map_or(0, |focus| focus + 1);
+        self.focus = (start..start + count).map(|index| index % count).find(|&index| self.widgets[index].focusable());
+        self.dirty = true;
+    }
+}
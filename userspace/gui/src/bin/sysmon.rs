@@ -0,0 +1,175 @@
+//! sysmon: the system monitor
+//!
+//! Shows the hostname, how long the system has been up, and how busy each
+//! CPU has been over the last second, from the CPU times the kernel keeps.
+//! Pause stops the updates and Refresh takes one at once. The hostname can
+//! be changed from the text box, by an administrator or in a UTS namespace
+//! of the program's own.
+
+#![cfg_attr(target_os = "rustos", no_std, no_main)]
+// Built anywhere else, as cargo test does, it only says where it runs
+#![cfg_attr(not(target_os = "rustos"), allow(dead_code))]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use gui::{App, Button, Label, List, Rect, Signal, TextBox, WidgetId, Window};
+use libsys::time::{self, CpuTimes};
+use libsys::{process, Errno};
+
+#[cfg(target_os = "rustos")]
+#[global_allocator]
+static ALLOCATOR: libsys::mem::PageAllocator = libsys::mem::PageAllocator;
+
+// The kernel's limit, nsproxy::MAX_HOSTNAME_LEN
+const MAX_HOSTNAME: usize = 64;
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Monitor {
+    hostname: WidgetId,
+    uptime: WidgetId,
+    cpus: WidgetId,
+    pause: WidgetId,
+    refresh: WidgetId,
+    new_name: WidgetId,
+    rename: WidgetId,
+    status: WidgetId,
+    paused: bool,
+    // Each CPU's times at the last update
+    last: Vec<CpuTimes>,
+}
+
+impl Monitor {
+    fn new(app: &mut App) -> Self {
+        let monitor = Self {
+            hostname: app.add(Label::new(Rect::new(8, 8, 304, 16), "")),
+            uptime: app.add(Label::new(Rect::new(8, 28, 304, 16), "")),
+            cpus: app.add(List::new(Rect::new(8, 52, 304, 120))),
+            pause: app.add(Button::new(Rect::new(8, 180, 96, 24), "Pause")),
+            refresh: app.add(Button::new(Rect::new(112, 180, 96, 24), "Refresh")),
+            new_name: app.add(TextBox::new(Rect::new(8, 212, 184, 24), MAX_HOSTNAME)),
+            rename: app.add(Button::new(Rect::new(200, 212, 112, 24), "Set hostname")),
+            status: app.add(Label::new(Rect::new(8, 242, 304, 16), "")),
+            paused: false,
+            last: Vec::new(),
+        };
+        app.focus(monitor.new_name);
+        monitor
+    }
+
+    fn update(&mut self, app: &mut App) {
+        let mut buf = [0u8; MAX_HOSTNAME + 1];
+        let hostname = match process::hostname(&mut buf) {
+            Ok(name) => format!("Host: {}", name),
+            Err(errno) => format!("Host: unknown ({})", errno),
+        };
+        set_label(app, self.hostname, &hostname);
+
+        let up = time::uptime().as_secs();
+        let uptime = format!("Up: {}d {:02}:{:02}:{:02}", up / 86400, up / 3600 % 24, up / 60 % 60, up % 60);
+        set_label(app, self.uptime, &uptime);
+
+        // CPUs are numbered from 0 with no gaps; the first missing ends them
+        let now: Vec<CpuTimes> = (0..).map_while(|cpu| time::cpu_times(cpu).ok()).collect();
+        let lines = now
+            .iter()
+            .enumerate()
+            .map(|(cpu, times)| match self.last.get(cpu) {
+                Some(last) => format!("CPU {:<3} {:>3}% busy", cpu, busy_percent(last, times)),
+                None => format!("CPU {:<3}   - ", cpu),
+            })
+            .collect();
+        if let Some(list) = app.get_mut::<List>(self.cpus) {
+            list.set_items(lines);
+        }
+        self.last = now;
+    }
+
+    fn handle(&mut self, app: &mut App, signal: Signal) {
+        match signal {
+            Signal::Tick if !self.paused => self.update(app),
+            Signal::Activated(id) if id == self.pause => {
+                self.paused = !self.paused;
+                if let Some(button) = app.get_mut::<Button>(self.pause) {
+                    button.set_label(if self.paused { "Resume" } else { "Pause" });
+                }
+            }
+            Signal::Activated(id) if id == self.refresh => self.update(app),
+            Signal::Activated(id) if id == self.rename || id == self.new_name => self.rename(app),
+            _ => {}
+        }
+    }
+
+    fn rename(&mut self, app: &mut App) {
+        let name: String = app.get::<TextBox>(self.new_name).map(|text| String::from(text.text().trim())).unwrap_or_default();
+        if name.is_empty() {
+            return;
+        }
+        let status = match process::set_hostname(&name) {
+            Ok(()) => {
+                if let Some(text) = app.get_mut::<TextBox>(self.new_name) {
+                    text.set_text("");
+                }
+                format!("Hostname set to {}", name)
+            }
+            Err(Errno::EPERM) => String::from("Only an administrator may set it"),
+            Err(errno) => format!("Not set: {}", errno),
+        };
+        set_label(app, self.status, &status);
+        self.update(app);
+    }
+}
+
+fn set_label(app: &mut App, id: WidgetId, text: &str) {
+    if let Some(label) = app.get_mut::<Label>(id) {
+        label.set_text(text);
+    }
+}
+
+// Of the time between two samples, how much was not idle
+fn busy_percent(before: &CpuTimes, after: &CpuTimes) -> u64 {
+    let total = |times: &CpuTimes| times.user_ns + times.kernel_ns + times.irq_ns + times.idle_ns;
+    let elapsed = total(after).saturating_sub(total(before));
+    let idle = after.idle_ns.saturating_sub(before.idle_ns);
+    if elapsed == 0 {
+        return 0;
+    }
+    elapsed.saturating_sub(idle) * 100 / elapsed
+}
+
+fn run() -> libsys::Result<()> {
+    let window = Window::new(c"System Monitor", 320, 264)?;
+    let mut app = App::new(window);
+    let mut monitor = Monitor::new(&mut app);
+    monitor.update(&mut app);
+    app.run(UPDATE_INTERVAL, |app, signal| monitor.handle(app, signal))
+}
+
+#[cfg(target_os = "rustos")]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    match run() {
+        Ok(()) => process::exit(0),
+        Err(errno) => {
+            libsys::eprintln!("sysmon: {}", errno);
+            process::exit(1)
+        }
+    }
+}
+
+#[cfg(target_os = "rustos")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    libsys::eprintln!("sysmon: {}", info);
+    process::exit(101)
+}
+
+#[cfg(not(target_os = "rustos"))]
+fn main() {
+    eprintln!("sysmon: runs only on RustOS");
+    std::process::exit(1)
+}
@@ -0,0 +1,219 @@
+//! Drawing into a surface
+
+use crate::font;
+
+/// A color as the surface holds it, 0x00RRGGBB
+pub type Color = u32;
+
+pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+pub const BLACK: Color = rgb(0, 0, 0);
+pub const WHITE: Color = rgb(255, 255, 255);
+pub const FACE: Color = rgb(212, 208, 200);
+pub const SHADOW: Color = rgb(128, 128, 128);
+pub const HIGHLIGHT: Color = rgb(10, 36, 106);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Self { x, y, width, height }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width as i32 && y < self.y + self.height as i32
+    }
+
+    /// Smaller by `by` on every side
+    pub fn inset(&self, by: u32) -> Self {
+        Self::new(self.x + by as i32, self.y + by as i32, self.width.saturating_sub(2 * by), self.height.saturating_sub(2 * by))
+    }
+}
+
+/// Pixels to draw on, clipped to their bounds
+pub struct Canvas<'a> {
+    pixels: &'a mut [Color],
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Canvas<'a> {
+    /// `pixels` holds `height` rows of `width`
+    pub fn new(pixels: &'a mut [Color], width: u32, height: u32) -> Self {
+        assert!(pixels.len() >= width as usize * height as usize);
+        Self { pixels, width, height }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: Color) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            self.pixels[y as usize * self.width as usize + x as usize] = color;
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        let x0 = rect.x.clamp(0, self.width as i32) as usize;
+        let y0 = rect.y.clamp(0, self.height as i32) as usize;
+        let x1 = (rect.x + rect.width as i32).clamp(0, self.width as i32) as usize;
+        let y1 = (rect.y + rect.height as i32).clamp(0, self.height as i32) as usize;
+        for y in y0..y1 {
+            let row = y * self.width as usize;
+            self.pixels[row + x0..row + x1].fill(color);
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        self.pixels.fill(color);
+    }
+
+    /// A line one pixel wide just inside `rect`
+    pub fn outline(&mut self, rect: Rect, color: Color) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let right = rect.x + rect.width as i32 - 1;
+        let bottom = rect.y + rect.height as i32 - 1;
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), color);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), color);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), color);
+    }
+
+    /// A raised edge, or a sunken one, as buttons and text boxes have
+    pub fn bevel(&mut self, rect: Rect, sunken: bool) {
+        let (light, dark) = if sunken { (SHADOW, WHITE) } else { (WHITE, SHADOW) };
+        let right = rect.x + rect.width as i32 - 1;
+        let bottom = rect.y + rect.height as i32 - 1;
+        self.fill_rect(Rect::new(rect.x, rect.y, rect.width, 1), light);
+        self.fill_rect(Rect::new(rect.x, rect.y, 1, rect.height), light);
+        self.fill_rect(Rect::new(rect.x, bottom, rect.width, 1), dark);
+        self.fill_rect(Rect::new(right, rect.y, 1, rect.height), dark);
+    }
+
+    /// Draw `text` with its top left corner at `x`, `y`, returning where it
+    /// ends
+    pub fn text(&mut self, x: i32, y: i32, text: &str, color: Color) -> i32 {
+        let mut at = x;
+        for c in text.chars() {
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                for column in 0..font::WIDTH as i32 {
+                    if bits & 1 << column != 0 {
+                        self.set_pixel(at + column, y + row as i32, color);
+                    }
+                }
+            }
+            at += font::WIDTH as i32;
+        }
+        at
+    }
+}
+
+/// How wide `text` is drawn
+pub fn text_width(text: &str) -> u32 {
+    text.chars().count() as u32 * font::WIDTH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: u32 = 6;
+    const H: u32 = 4;
+
+    // The surface as rows of characters: '.' for 0 and '#' for anything else
+    fn picture(pixels: &[Color]) -> [[u8; W as usize]; H as usize] {
+        core::array::from_fn(|y| core::array::from_fn(|x| if pixels[y * W as usize + x] == 0 { b'.' } else { b'#' }))
+    }
+
+    fn rows(picture: &[&str; H as usize]) -> [[u8; W as usize]; H as usize] {
+        core::array::from_fn(|y| picture[y].as_bytes().try_into().unwrap())
+    }
+
+    #[test]
+    fn packs_colors() {
+        assert_eq!(rgb(0x12, 0x34, 0x56), 0x123456);
+        assert_eq!(WHITE, 0xFFFFFF);
+    }
+
+    #[test]
+    fn rect_contains_and_insets() {
+        let rect = Rect::new(-2, 3, 4, 2);
+        assert!(rect.contains(-2, 3));
+        assert!(rect.contains(1, 4));
+        assert!(!rect.contains(2, 4));
+        assert!(!rect.contains(1, 5));
+        assert_eq!(Rect::new(1, 1, 10, 6).inset(2), Rect::new(3, 3, 6, 2));
+        assert_eq!(Rect::new(0, 0, 3, 3).inset(2), Rect::new(2, 2, 0, 0));
+    }
+
+    #[test]
+    fn clips_to_the_surface() {
+        let mut pixels = [0; (W * H) as usize];
+        let mut canvas = Canvas::new(&mut pixels, W, H);
+        canvas.fill_rect(Rect::new(-3, 2, 5, 10), 1);
+        canvas.fill_rect(Rect::new(4, -1, 9, 2), 1);
+        canvas.fill_rect(Rect::new(10, 10, 2, 2), 1);
+        canvas.set_pixel(-1, 0, 1);
+        canvas.set_pixel(3, 0, 1);
+        canvas.set_pixel(6, 0, 1);
+        canvas.set_pixel(0, 4, 1);
+        assert_eq!(picture(&pixels), rows(&["...###", "......", "##....", "##...."]));
+    }
+
+    #[test]
+    fn outlines_and_bevels() {
+        let mut pixels = [0; (W * H) as usize];
+        let mut canvas = Canvas::new(&mut pixels, W, H);
+        canvas.outline(Rect::new(1, 0, 4, 4), 1);
+        canvas.outline(Rect::new(0, 0, 0, 4), 1);
+        assert_eq!(picture(&pixels), rows(&[".####.", ".#..#.", ".#..#.", ".####."]));
+
+        let mut canvas = Canvas::new(&mut pixels, W, H);
+        canvas.clear(0);
+        canvas.bevel(canvas.bounds(), false);
+        assert_eq!(pixels[0], WHITE);
+        assert_eq!(pixels[(W - 1) as usize], SHADOW);
+        assert_eq!(pixels[(W * H - 1) as usize], SHADOW);
+        assert_eq!(pixels[(W * (H - 1)) as usize], SHADOW);
+
+        let mut canvas = Canvas::new(&mut pixels, W, H);
+        canvas.bevel(canvas.bounds(), true);
+        assert_eq!(pixels[0], SHADOW);
+        assert_eq!(pixels[(W * H - 1) as usize], WHITE);
+    }
+
+    #[test]
+    fn draws_text_a_glyph_at_a_time() {
+        let mut pixels = [0; 16 * 8];
+        let mut canvas = Canvas::new(&mut pixels, 16, 8);
+        assert_eq!(canvas.text(0, 0, "-", 1), 8);
+        // '-' is row 3, bits 0 to 5
+        assert_eq!(&pixels[3 * 16..3 * 16 + 8], &[1, 1, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(pixels.iter().filter(|pixel| **pixel != 0).count(), 6);
+
+        // The second glyph starts off the right edge and is cut
+        let mut canvas = Canvas::new(&mut pixels, 16, 8);
+        assert_eq!(canvas.text(12, 0, "--", 1), 28);
+        assert_eq!(&pixels[3 * 16 + 12..4 * 16], &[1, 1, 1, 1]);
+        assert_eq!(text_width("héllo"), 40);
+    }
+}
@@ -0,0 +1,56 @@
+//! gui: a window toolkit for graphical programs on RustOS
+//!
+//! Each window is shared memory between the program and the kernel's
+//! compositor: the program draws into the window's surface, then asks for
+//! the part it changed to be put on the screen. Input comes back the same
+//! way, as events queued on the window. The system calls are in [`sys`]
+//! and their kernel side in `kernel/src/graphics/client.rs`.
+//!
+//! | Module     | What                                                        |
+//! |------------|-------------------------------------------------------------|
+//! | [`window`] | a window and its surface                                    |
+//! | [`canvas`] | rectangles, edges and text on a surface                     |
+//! | [`widget`] | button, label, text box and list                            |
+//! | [`app`]    | the event loop, focus and redrawing                         |
+//! | [`font`]   | the 8x8 font                                                |
+//! | [`sys`]    | the system calls and the events, as the kernel has them     |
+//!
+//! A program opens a [`Window`], adds its widgets to an [`App`], and runs
+//! it:
+//!
+//! ```ignore
+//! let window = Window::new(c"Hello", 200, 80)?;
+//! let mut app = App::new(window);
+//! let button = app.add(Button::new(Rect::new(60, 24, 80, 24), "Quit"));
+//! app.run(Duration::ZERO, |app, signal| {
+//!     if signal == Signal::Activated(button) {
+//!         app.quit();
+//!     }
+//! })?;
+//! ```
+//!
+//! Programs build like any other on libsys, for its target; `sysmon`, in
+//! `src/bin`, is a system monitor built on the toolkit:
+//!
+//! ```text
+//! cargo +nightly build --release --bin sysmon \
+//!     --target userspace/libsys/x86_64-unknown-rustos.json \
+//!     -Zbuild-std=core,alloc -Zbuild-std-features=compiler-builtins-mem \
+//!     -Zjson-target-spec
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod app;
+pub mod canvas;
+pub mod font;
+pub mod sys;
+pub mod widget;
+pub mod window;
+
+pub use app::{App, Signal, WidgetId};
+pub use canvas::{Canvas, Color, Rect};
+pub use widget::{Button, Label, List, Response, TextBox, Widget};
+pub use window::Window;
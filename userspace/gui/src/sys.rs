@@ -0,0 +1,114 @@
+//! The window system calls
+//!
+//! The kernel's side is `kernel/src/graphics/client.rs`. A window is a
+//! descriptor; mapped with `MAP_SHARED` it is the surface, `width * height`
+//! pixels of 0x00RRGGBB a row at a time. Drawing there shows nothing until
+//! [`draw_window`] puts it on the screen.
+
+use core::ffi::CStr;
+use core::mem::MaybeUninit;
+use libsys::error::check;
+use libsys::raw::{self, nr};
+use libsys::{Errno, Result};
+
+/// The pointer moved
+pub const EVENT_POINTER_MOVE: u32 = 1;
+/// A button was pressed: `code` is which
+pub const EVENT_BUTTON_DOWN: u32 = 2;
+/// A button was let go: `code` is which
+pub const EVENT_BUTTON_UP: u32 = 3;
+/// The wheel turned: `code` is how far, as an `i32`
+pub const EVENT_WHEEL: u32 = 4;
+/// A character was typed: `code` is the Unicode scalar value
+pub const EVENT_CHAR: u32 = 5;
+pub const EVENT_FOCUS_IN: u32 = 6;
+pub const EVENT_FOCUS_OUT: u32 = 7;
+/// The close button was clicked; the window stays until it is destroyed
+pub const EVENT_CLOSE: u32 = 8;
+
+pub const BUTTON_LEFT: u32 = 1;
+pub const BUTTON_RIGHT: u32 = 2;
+pub const BUTTON_MIDDLE: u32 = 3;
+
+/// Longest window title, in bytes
+pub const MAX_TITLE: usize = 128;
+
+/// A window event; pointer positions are relative to the client area
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub kind: u32,
+    pub code: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// A rectangle of the surface to put on the screen
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Damage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScreenInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u32,
+}
+
+/// Open a window whose client area is `width` by `height`, at `x`, `y` or,
+/// when None, wherever the desktop puts it
+pub fn create_window(position: Option<(i32, i32)>, width: u32, height: u32, title: &CStr) -> Result<usize> {
+    let (x, y) = position.map_or((usize::MAX, usize::MAX), |(x, y)| (x as usize, y as usize));
+    let title = title.as_ptr() as usize;
+    check(unsafe { raw::syscall5(nr::CREATE_WINDOW, x, y, width as usize, height as usize, title) })
+}
+
+pub fn destroy_window(fd: usize) -> Result<()> {
+    check(unsafe { raw::syscall1(nr::DESTROY_WINDOW, fd) }).map(|_| ())
+}
+
+/// Put `damage` of the surface on the screen, or all of it when None
+pub fn draw_window(fd: usize, damage: Option<&Damage>) -> Result<()> {
+    let damage = damage.map_or(0, |damage| damage as *const Damage as usize);
+    check(unsafe { raw::syscall2(nr::DRAW_WINDOW, fd, damage) }).map(|_| ())
+}
+
+/// The window's next event, or None when none is waiting
+pub fn next_event(fd: usize) -> Result<Option<Event>> {
+    let mut event = MaybeUninit::<Event>::uninit();
+    match check(unsafe { raw::syscall2(nr::HANDLE_EVENT, fd, event.as_mut_ptr() as usize) }) {
+        Ok(_) => Ok(Some(unsafe { event.assume_init() })),
+        Err(Errno::EAGAIN) => Ok(None),
+        Err(errno) => Err(errno),
+    }
+}
+
+pub fn screen_info() -> Result<ScreenInfo> {
+    let mut info = ScreenInfo::default();
+    check(unsafe { raw::syscall1(nr::GET_SCREEN_INFO, &mut info as *mut ScreenInfo as usize) })?;
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{offset_of, size_of};
+
+    // The kernel reads and writes these as its own structures of the same
+    // names, in graphics/client.rs and syscall/handlers.rs
+    #[test]
+    fn structures_match_the_kernel() {
+        assert_eq!(size_of::<Event>(), 16);
+        assert_eq!((offset_of!(Event, kind), offset_of!(Event, code), offset_of!(Event, x), offset_of!(Event, y)), (0, 4, 8, 12));
+        assert_eq!(size_of::<Damage>(), 16);
+        assert_eq!(offset_of!(Damage, height), 12);
+        assert_eq!(size_of::<ScreenInfo>(), 12);
+        assert_eq!(offset_of!(ScreenInfo, bpp), 8);
+    }
+}
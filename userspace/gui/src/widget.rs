@@ -0,0 +1,318 @@
+//! Widgets: buttons, labels, text boxes and lists
+//!
+//! A widget has a place in the window, draws itself there, and takes the
+//! window's events: pointer events at any position, which it ignores
+//! outside its rectangle, and typing when it has the focus. What it did
+//! with an event it answers as a [`Response`].
+
+use crate::canvas::{text_width, Canvas, Rect, BLACK, FACE, HIGHLIGHT, SHADOW, WHITE};
+use crate::font;
+use crate::sys::{Event, BUTTON_LEFT, EVENT_BUTTON_DOWN, EVENT_BUTTON_UP, EVENT_CHAR, EVENT_WHEEL};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::Any;
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+/// What a widget did with an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    /// Nothing: it was not for this widget
+    Ignored,
+    /// It changed how the widget looks
+    Redraw,
+    /// The widget was used: a button clicked, Enter pressed in a text box,
+    /// an item chosen in a list
+    Activated,
+}
+
+pub trait Widget: Any {
+    fn rect(&self) -> Rect;
+
+    fn draw(&self, canvas: &mut Canvas, focused: bool);
+
+    fn handle(&mut self, event: &Event, focused: bool) -> Response;
+
+    /// Whether a click gives it the focus, for typing
+    fn focusable(&self) -> bool {
+        false
+    }
+}
+
+fn is_click(event: &Event, kind: u32) -> bool {
+    event.kind == kind && event.code == BUTTON_LEFT
+}
+
+// Text in a line `width` wide, cut short to fit
+fn fit(text: &str, width: u32) -> &str {
+    let fits = (width / font::WIDTH) as usize;
+    text.char_indices().nth(fits).map_or(text, |(end, _)| &text[..end])
+}
+
+// The top of a line of text centred in `rect`
+fn middle(rect: Rect) -> i32 {
+    rect.y + (rect.height.saturating_sub(font::HEIGHT) / 2) as i32
+}
+
+/// A push button
+pub struct Button {
+    rect: Rect,
+    label: String,
+    pressed: bool,
+}
+
+impl Button {
+    pub fn new(rect: Rect, label: &str) -> Self {
+        Self { rect, label: String::from(label), pressed: false }
+    }
+
+    pub fn set_label(&mut self, label: &str) {
+        self.label = String::from(label);
+    }
+}
+
+impl Widget for Button {
+    fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    fn draw(&self, canvas: &mut Canvas, focused: bool) {
+        canvas.fill_rect(self.rect, FACE);
+        canvas.bevel(self.rect, self.pressed);
+        if focused {
+            canvas.outline(self.rect.inset(3), SHADOW);
+        }
+        let label = fit(&self.label, self.rect.width.saturating_sub(8));
+        let x = self.rect.x + (self.rect.width.saturating_sub(text_width(label)) / 2) as i32;
+        let shift = i32::from(self.pressed);
+        canvas.text(x + shift, middle(self.rect) + shift, label, BLACK);
+    }
+
+    fn handle(&mut self, event: &Event, focused: bool) -> Response {
+        let inside = self.rect.contains(event.x, event.y);
+        if is_click(event, EVENT_BUTTON_DOWN) && inside {
+            self.pressed = true;
+            Response::Redraw
+        } else if is_click(event, EVENT_BUTTON_UP) && self.pressed {
+            self.pressed = false;
+            if inside {
+                Response::Activated
+            } else {
+                Response::Redraw
+            }
+        } else if event.kind == EVENT_CHAR && focused && matches!(char::from_u32(event.code), Some(' ' | '\n' | '\r')) {
+            Response::Activated
+        } else {
+            Response::Ignored
+        }
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+}
+
+/// A line of text
+pub struct Label {
+    rect: Rect,
+    text: String,
+}
+
+impl Label {
+    pub fn new(rect: Rect, text: &str) -> Self {
+        Self { rect, text: String::from(text) }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text.clear();
+        self.text.push_str(text);
+    }
+}
+
+impl Widget for Label {
+    fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    fn draw(&self, canvas: &mut Canvas, _focused: bool) {
+        canvas.fill_rect(self.rect, FACE);
+        canvas.text(self.rect.x, middle(self.rect), fit(&self.text, self.rect.width), BLACK);
+    }
+
+    fn handle(&mut self, _event: &Event, _focused: bool) -> Response {
+        Response::Ignored
+    }
+}
+
+/// A line of text to edit, typed at its end
+pub struct TextBox {
+    rect: Rect,
+    text: String,
+    max_length: usize,
+}
+
+impl TextBox {
+    pub fn new(rect: Rect, max_length: usize) -> Self {
+        Self { rect, text: String::new(), max_length }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().take(self.max_length).collect();
+    }
+}
+
+impl Widget for TextBox {
+    fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    fn draw(&self, canvas: &mut Canvas, focused: bool) {
+        canvas.fill_rect(self.rect, WHITE);
+        canvas.bevel(self.rect, true);
+        // The end of the text, and the caret after it, stay in view
+        let area = self.rect.inset(4);
+        let fits = (area.width / font::WIDTH).saturating_sub(1) as usize;
+        let skip = self.text.chars().count().saturating_sub(fits);
+        let shown = self.text.char_indices().nth(skip).map_or("", |(start, _)| &self.text[start..]);
+        let end = canvas.text(area.x, middle(self.rect), shown, BLACK);
+        if focused {
+            canvas.fill_rect(Rect::new(end, middle(self.rect), 1, font::HEIGHT), BLACK);
+        }
+    }
+
+    fn handle(&mut self, event: &Event, focused: bool) -> Response {
+        if event.kind != EVENT_CHAR || !focused {
+            return Response::Ignored;
+        }
+        match char::from_u32(event.code) {
+            Some('\n' | '\r') => Response::Activated,
+            Some(BACKSPACE | DELETE) => {
+                self.text.pop();
+                Response::Redraw
+            }
+            Some(c) if !c.is_control() && self.text.chars().count() < self.max_length => {
+                self.text.push(c);
+                Response::Redraw
+            }
+            _ => Response::Ignored,
+        }
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+}
+
+/// Lines of text, one of which may be selected; the wheel scrolls it
+pub struct List {
+    rect: Rect,
+    items: Vec<String>,
+    selected: Option<usize>,
+    scroll: usize,
+}
+
+impl List {
+    const ROW_HEIGHT: u32 = font::HEIGHT + 4;
+
+    pub fn new(rect: Rect) -> Self {
+        Self { rect, items: Vec::new(), selected: None, scroll: 0 }
+    }
+
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    /// Replace the items, keeping the selection where it is if it is still
+    /// in the list
+    pub fn set_items(&mut self, items: Vec<String>) {
+        self.items = items;
+        self.selected = self.selected.filter(|&selected| selected < self.items.len());
+        self.scroll = self.scroll.min(self.items.len().saturating_sub(self.rows()));
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    fn rows(&self) -> usize {
+        (self.rect.height.saturating_sub(4) / Self::ROW_HEIGHT) as usize
+    }
+
+    fn select(&mut self, selected: usize) -> Response {
+        if self.selected == Some(selected) {
+            return Response::Ignored;
+        }
+        self.selected = Some(selected);
+        if selected < self.scroll {
+            self.scroll = selected;
+        } else if selected >= self.scroll + self.rows() {
+            self.scroll = selected + 1 - self.rows();
+        }
+        Response::Activated
+    }
+}
+
+impl Widget for List {
+    fn rect(&self) -> Rect {
+        self.rect
+    }
+
+    fn draw(&self, canvas: &mut Canvas, focused: bool) {
+        canvas.fill_rect(self.rect, WHITE);
+        canvas.bevel(self.rect, true);
+        let area = self.rect.inset(2);
+        for (row, item) in self.items.iter().enumerate().skip(self.scroll).take(self.rows()) {
+            let line = Rect::new(area.x, area.y + ((row - self.scroll) as u32 * Self::ROW_HEIGHT) as i32, area.width, Self::ROW_HEIGHT);
+            let color = if self.selected == Some(row) {
+                canvas.fill_rect(line, if focused { HIGHLIGHT } else { SHADOW });
+                WHITE
+            } else {
+                BLACK
+            };
+            canvas.text(line.x + 2, middle(line), fit(item, line.width.saturating_sub(4)), color);
+        }
+    }
+
+    fn handle(&mut self, event: &Event, focused: bool) -> Response {
+        let area = self.rect.inset(2);
+        match event.kind {
+            EVENT_BUTTON_DOWN if event.code == BUTTON_LEFT && area.contains(event.x, event.y) => {
+                let row = self.scroll + ((event.y - area.y) as u32 / Self::ROW_HEIGHT) as usize;
+                if row < self.items.len() {
+                    self.select(row)
+                } else {
+                    Response::Ignored
+                }
+            }
+            EVENT_WHEEL if self.rect.contains(event.x, event.y) => {
+                let last = self.items.len().saturating_sub(self.rows());
+                let scroll = (self.scroll as i64 + i64::from(event.code as i32)).clamp(0, last as i64) as usize;
+                if scroll == self.scroll {
+                    return Response::Ignored;
+                }
+                self.scroll = scroll;
+                Response::Redraw
+            }
+            // j and k move the selection, as in vi
+            EVENT_CHAR if focused && !self.items.is_empty() => match char::from_u32(event.code) {
+                Some('j') => self.select(self.selected.map_or(0, |selected| (selected + 1).min(self.items.len() - 1))),
+                Some('k') => self.select(self.selected.map_or(0, |selected| selected.saturating_sub(1))),
+                _ => Response::Ignored,
+            },
+            _ => Response::Ignored,
+        }
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+}
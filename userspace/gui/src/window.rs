@@ -0,0 +1,88 @@
+//! A window and its surface
+
+use crate::canvas::{Canvas, Color, Rect};
+use crate::sys::{self, Damage, Event};
+use core::ffi::CStr;
+use core::slice;
+use libsys::mem::{self, MAP_SHARED, PROT_READ, PROT_WRITE};
+use libsys::Result;
+
+/// A window on the desktop, destroyed when dropped
+pub struct Window {
+    fd: usize,
+    pixels: *mut Color,
+    width: u32,
+    height: u32,
+}
+
+impl Window {
+    /// Open a window whose client area is `width` by `height`, wherever the
+    /// desktop puts it
+    pub fn new(title: &CStr, width: u32, height: u32) -> Result<Self> {
+        Self::open(None, title, width, height)
+    }
+
+    /// Open a window at `x`, `y` on the screen
+    pub fn at(x: i32, y: i32, title: &CStr, width: u32, height: u32) -> Result<Self> {
+        Self::open(Some((x, y)), title, width, height)
+    }
+
+    fn open(position: Option<(i32, i32)>, title: &CStr, width: u32, height: u32) -> Result<Self> {
+        let fd = sys::create_window(position, width, height, title)?;
+        let length = Self::length(width, height);
+        match unsafe { mem::mmap(core::ptr::null_mut(), length, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0) } {
+            Ok(pixels) => Ok(Self { fd, pixels: pixels.cast(), width, height }),
+            Err(errno) => {
+                let _ = sys::destroy_window(fd);
+                Err(errno)
+            }
+        }
+    }
+
+    fn length(width: u32, height: u32) -> usize {
+        width as usize * height as usize * 4
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn as_raw_fd(&self) -> usize {
+        self.fd
+    }
+
+    /// The surface, to draw on; nothing shows until [`Window::present`]
+    pub fn canvas(&mut self) -> Canvas<'_> {
+        let pixels = unsafe { slice::from_raw_parts_mut(self.pixels, self.width as usize * self.height as usize) };
+        Canvas::new(pixels, self.width, self.height)
+    }
+
+    /// Put `rect` of the surface on the screen, or all of it when None
+    pub fn present(&self, rect: Option<Rect>) -> Result<()> {
+        let damage = rect.map(|rect| Damage {
+            x: rect.x.max(0) as u32,
+            y: rect.y.max(0) as u32,
+            width: rect.width,
+            height: rect.height,
+        });
+        sys::draw_window(self.fd, damage.as_ref())
+    }
+
+    /// The next event, or None when none is waiting
+    pub fn next_event(&self) -> Result<Option<Event>> {
+        sys::next_event(self.fd)
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = mem::munmap(self.pixels.cast(), Self::length(self.width, self.height));
+        }
+        let _ = sys::destroy_window(self.fd);
+    }
+}
@@ -0,0 +1,150 @@
+//! svcctl: start, stop and report on the services init runs
+
+use clap::{Parser, Subcommand};
+use init::control::{self, Request, Response, ServiceStatus};
+use std::path::PathBuf;
+use std::process;
+
+const FAILURE: i32 = 1;
+// As LSB init scripts answer `status`
+const NOT_RUNNING: i32 = 3;
+
+#[derive(Parser)]
+#[command(name = "svcctl")]
+#[command(author = "RustOS Contributors")]
+#[command(version = "1.0.0")]
+#[command(about = "Control the services init runs", long_about = None)]
+#[command(after_help = "Exit codes:
+  0  success
+  1  failure
+  2  bad command line
+  3  status of a service that is not running")]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    #[arg(long, value_name = "PATH", global = true, default_value = control::SOCKET)]
+    socket: PathBuf,
+
+    #[arg(long, global = true, help = "Print status as JSON")]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    #[command(about = "Start services, and what they require")]
+    Start {
+        #[arg(required = true)]
+        services: Vec<String>,
+    },
+
+    #[command(about = "Stop services, and what requires them")]
+    Stop {
+        #[arg(required = true)]
+        services: Vec<String>,
+    },
+
+    #[command(about = "Stop services and start them again")]
+    Restart {
+        #[arg(required = true)]
+        services: Vec<String>,
+    },
+
+    #[command(about = "Show one service, or all of them")]
+    Status {
+        service: Option<String>,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let (requests, single): (Vec<Request>, bool) = match cli.command {
+        Commands::Start { services } => (services.into_iter().map(|service| Request::Start { service }).collect(), false),
+        Commands::Stop { services } => (services.into_iter().map(|service| Request::Stop { service }).collect(), false),
+        Commands::Restart { services } => (services.into_iter().map(|service| Request::Restart { service }).collect(), false),
+        Commands::Status { service } => {
+            let single = service.is_some();
+            (vec![Request::Status { service }], single)
+        }
+    };
+
+    let mut code = 0;
+    for request in requests {
+        match control::send(&cli.socket, &request) {
+            Ok(Response::Done { message }) => println!("{}", message),
+            Ok(Response::Status { services }) => {
+                if cli.json {
+                    println!("{}", serde_json::to_string_pretty(&services).unwrap_or_default());
+                } else if single {
+                    services.iter().for_each(print_service);
+                } else {
+                    print_table(&services);
+                }
+                if single && services.iter().any(|service| service.state != "running") {
+                    code = NOT_RUNNING;
+                }
+            }
+            Ok(Response::Error { message }) => {
+                eprintln!("svcctl: {}", message);
+                code = FAILURE;
+            }
+            Err(e) => {
+                eprintln!("svcctl: {}", e);
+                process::exit(FAILURE);
+            }
+        }
+    }
+    process::exit(code);
+}
+
+fn print_service(service: &ServiceStatus) {
+    if service.description.is_empty() {
+        println!("{}", service.name);
+    } else {
+        println!("{} - {}", service.name, service.description);
+    }
+
+    let mut state = service.state.clone();
+    match (service.pid, service.uptime_seconds) {
+        (Some(pid), Some(uptime)) => state.push_str(&format!(" (pid {}, up {})", pid, duration(uptime))),
+        (Some(pid), None) => state.push_str(&format!(" (pid {})", pid)),
+        _ => {}
+    }
+    println!("  State:     {}", state);
+    if let Some(detail) = &service.detail {
+        println!("  Detail:    {}", detail);
+    }
+    println!("  Enabled:   {}", if service.enabled { "yes" } else { "no" });
+    println!("  Restarts:  {}", service.restarts);
+    if let Some(last_exit) = &service.last_exit {
+        println!("  Last exit: {}", last_exit);
+    }
+}
+
+fn print_table(services: &[ServiceStatus]) {
+    let width = services.iter().map(|service| service.name.len()).max().unwrap_or(0).max("SERVICE".len());
+    println!("{:<width$}  {:<10}  {:>7}  {:>8}  {:>8}  DESCRIPTION", "SERVICE", "STATE", "PID", "UPTIME", "RESTARTS");
+    for service in services {
+        let pid = service.pid.map_or("-".to_string(), |pid| pid.to_string());
+        let uptime = service.uptime_seconds.map_or("-".to_string(), duration);
+        println!(
+            "{:<width$}  {:<10}  {:>7}  {:>8}  {:>8}  {}",
+            service.name, service.state, pid, uptime, service.restarts, service.description
+        );
+    }
+}
+
+// The two largest units of a duration, as in "3m 12s"
+fn duration(seconds: u64) -> String {
+    let (days, hours, minutes, seconds) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
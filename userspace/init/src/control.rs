@@ -0,0 +1,83 @@
+//! The control socket
+//!
+//! `svcctl` connects to init's Unix socket, writes one request as a line of
+//! JSON and reads one response the same way. The socket is only open to
+//! root.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+pub const SOCKET: &str = "/run/init.sock";
+// Longest request or response line accepted
+pub const MAX_MESSAGE: u64 = 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Start { service: String },
+    Stop { service: String },
+    Restart { service: String },
+    // One service, or all of them in start order
+    Status { service: Option<String> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum Response {
+    Done { message: String },
+    Status { services: Vec<ServiceStatus> },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub description: String,
+    pub enabled: bool,
+    // "running", "stopped", "failed", ...
+    pub state: String,
+    // Why the service is in its state, when that is not plain
+    pub detail: Option<String>,
+    pub pid: Option<u32>,
+    pub uptime_seconds: Option<u64>,
+    // Restarts since init started
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+}
+
+// Send a request to init and wait for its response
+pub fn send(socket: &Path, request: &Request) -> Result<Response, Box<dyn Error>> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|e| format!("cannot reach init at {}: {}", socket.display(), e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    write_message(&mut stream, request)?;
+    read_message(&mut stream)?.ok_or_else(|| "init closed the connection without answering".into())
+}
+
+pub fn write_message<T: Serialize>(stream: &mut impl Write, message: &T) -> Result<(), Box<dyn Error>> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// One message, or None at end of stream
+pub fn read_message<T: for<'de> Deserialize<'de>>(stream: &mut impl Read) -> Result<Option<T>, Box<dyn Error>> {
+    let mut line = Vec::new();
+    BufReader::new(stream.take(MAX_MESSAGE)).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err("message too long or cut short".into());
+    }
+    Ok(Some(serde_json::from_slice(&line)?))
+}
@@ -0,0 +1,5 @@
+//! What `init` and `svcctl` share: service unit files and the control
+//! protocol between them
+
+pub mod control;
+pub mod unit;
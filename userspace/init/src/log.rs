@@ -0,0 +1,58 @@
+//! The system log
+//!
+//! Lines go to the syslog daemon listening on /dev/log, in the BSD format
+//! with the daemon facility. Until one is listening, and whenever sending
+//! fails, they go to init's standard error, which is the console.
+
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const FACILITY_DAEMON: u8 = 3;
+// Longer lines are cut, as most syslog daemons would
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Priority {
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+pub struct SystemLog {
+    socket: Mutex<Option<UnixDatagram>>,
+}
+
+impl SystemLog {
+    pub fn new() -> SystemLog {
+        SystemLog { socket: Mutex::new(None) }
+    }
+
+    pub fn write(&self, tag: &str, pid: u32, priority: Priority, message: &str) {
+        let mut end = message.len().min(MAX_LINE);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let message = &message[..end];
+        let line = format!("<{}>{}[{}]: {}", FACILITY_DAEMON * 8 + priority as u8, tag, pid, message);
+
+        let mut socket = self.socket.lock().unwrap();
+        if socket.is_none() {
+            *socket = UnixDatagram::unbound()
+                .and_then(|socket| socket.connect(SYSLOG_SOCKET).map(|()| socket))
+                .ok();
+        }
+        let sent = socket.as_ref().is_some_and(|socket| socket.send(line.as_bytes()).is_ok());
+        if !sent {
+            // The daemon may have restarted; connect afresh next time
+            *socket = None;
+            eprintln!("{}[{}]: {}", tag, pid, message);
+        }
+    }
+
+    // A message from init itself
+    pub fn init(&self, priority: Priority, message: &str) {
+        self.write("init", std::process::id(), priority, message);
+    }
+}
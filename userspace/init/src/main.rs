@@ -0,0 +1,139 @@
+//! init: the first userspace process, and the service manager
+//!
+//! It loads the service units in the unit directory, starts the enabled
+//! ones in dependency order and keeps them running as their restart
+//! policies say, sending what they print to the system log. As the first
+//! process it also reaps every orphan handed to it. `svcctl` asks it to
+//! start, stop and report on services over the control socket.
+//!
+//! SIGTERM or SIGINT stops every service, dependents first, and ends init.
+//!
+//! It needs the standard library and Unix calls RustOS does not have yet,
+//! such as pipes, Unix sockets and signal handlers, so for now it runs on a
+//! Unix host.
+
+mod log;
+mod supervisor;
+
+use clap::Parser;
+use init::control::{self, Request, Response};
+use init::unit;
+use log::{Priority, SystemLog};
+use std::error::Error;
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use supervisor::Supervisor;
+
+// Longest wait between passes over the services
+const TICK: Duration = Duration::from_millis(100);
+// How long a svcctl client may take to send its request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Parser)]
+#[command(name = "init")]
+#[command(author = "RustOS Contributors")]
+#[command(version = "1.0.0")]
+#[command(about = "First userspace process and service manager for RustOS", long_about = None)]
+struct Cli {
+    #[arg(long, value_name = "DIR", default_value = unit::UNIT_DIR, help = "Directory of service unit files")]
+    units: PathBuf,
+
+    #[arg(long, value_name = "PATH", default_value = control::SOCKET, help = "Control socket for svcctl")]
+    socket: PathBuf,
+}
+
+type Envelope = (Request, Sender<Response>);
+
+extern "C" fn request_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let log = Arc::new(SystemLog::new());
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+
+    let (units, problems) = unit::load(&cli.units);
+    for problem in &problems {
+        log.init(Priority::Err, problem);
+    }
+    log.init(Priority::Info, &format!("loaded {} services from {}", units.len(), cli.units.display()));
+
+    let (requests, incoming) = mpsc::channel::<Envelope>();
+    if let Err(e) = listen(&cli.socket, requests) {
+        let message = format!("cannot open control socket {}: {}; svcctl will not work", cli.socket.display(), e);
+        log.init(Priority::Err, &message);
+    }
+
+    let mut supervisor = Supervisor::new(units, Arc::clone(&log));
+    supervisor.boot();
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        match incoming.recv_timeout(TICK) {
+            Ok((request, reply)) => {
+                let _ = reply.send(supervisor.handle(request, Instant::now()));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // No control socket, so nothing to wait on but time
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(TICK),
+        }
+        let now = Instant::now();
+        supervisor.reap(now);
+        supervisor.step(now);
+    }
+
+    log.init(Priority::Notice, "stopping all services");
+    supervisor.shutdown();
+    let _ = fs::remove_file(&cli.socket);
+}
+
+// Take requests on the control socket from a thread of its own, passing
+// each to the main loop and its response back
+fn listen(path: &Path, requests: Sender<Envelope>) -> io::Result<()> {
+    // Left over from an earlier run
+    let _ = fs::remove_file(path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Only root may connect, from the moment the socket exists
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = bound?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = serve(&mut stream, &requests);
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: &mut UnixStream, requests: &Sender<Envelope>) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let response = match control::read_message::<Request>(stream) {
+        Ok(Some(request)) => {
+            let (reply, answer) = mpsc::channel();
+            requests.send((request, reply))?;
+            answer.recv()?
+        }
+        Ok(None) => return Ok(()),
+        Err(e) => Response::Error { message: format!("bad request: {}", e) },
+    };
+    control::write_message(stream, &response)
+}
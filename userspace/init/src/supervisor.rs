@@ -0,0 +1,600 @@
+//! Starting, watching and stopping services
+//!
+//! Services are kept in start order, so one pass over them in that order,
+//! made every tick of the main loop, sees each service's dependencies
+//! settled before the service itself. A pass starts wanted services whose
+//! dependencies allow it, stops running ones that are no longer wanted or
+//! have lost a requirement, and moves on from restart delays and stop
+//! timeouts that have run out. Exits are picked up by reaping, which also
+//! collects orphans handed to init.
+
+use crate::log::{Priority, SystemLog};
+use init::control::{Request, Response, ServiceStatus};
+use init::unit::{Restart, Unit};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A service that ran this long had started fine, so a restart after it
+// exits waits only the first delay again
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+// Longest line of output sent to the log in one piece
+const MAX_OUTPUT_LINE: u64 = 4096;
+// How often shutdown checks whether every service has stopped
+const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+
+enum State {
+    Stopped,
+    // Wanted, and waiting on the services it depends on
+    Waiting,
+    Running { pid: u32, since: Instant },
+    // Sent SIGTERM; SIGKILL follows at the deadline
+    Stopping { pid: u32, deadline: Instant, killed: bool },
+    // Waiting to restart
+    Backoff { until: Instant },
+    Failed(String),
+}
+
+struct Service {
+    unit: Unit,
+    state: State,
+    // Whether it should be running
+    wanted: bool,
+    restarts: u32,
+    // Restarts since it last ran for STABLE_AFTER
+    failures: u32,
+    last_exit: Option<String>,
+}
+
+impl Service {
+    fn running(&self) -> bool {
+        matches!(self.state, State::Running { .. })
+    }
+
+    fn pid(&self) -> Option<u32> {
+        match self.state {
+            State::Running { pid, .. } | State::Stopping { pid, .. } => Some(pid),
+            _ => None,
+        }
+    }
+}
+
+pub struct Supervisor {
+    // In start order
+    services: Vec<Service>,
+    log: Arc<SystemLog>,
+}
+
+impl Supervisor {
+    pub fn new(units: Vec<Unit>, log: Arc<SystemLog>) -> Supervisor {
+        let services = units
+            .into_iter()
+            .map(|unit| Service {
+                unit,
+                state: State::Stopped,
+                wanted: false,
+                restarts: 0,
+                failures: 0,
+                last_exit: None,
+            })
+            .collect();
+        Supervisor { services, log }
+    }
+
+    // Want every enabled service, and so what each requires
+    pub fn boot(&mut self) {
+        for index in 0..self.services.len() {
+            if self.services[index].unit.enabled {
+                self.want(index);
+            }
+        }
+    }
+
+    pub fn handle(&mut self, request: Request, now: Instant) -> Response {
+        let name = match &request {
+            Request::Start { service } | Request::Stop { service } | Request::Restart { service } => Some(service),
+            Request::Status { service } => service.as_ref(),
+        };
+        let index = match name.map(|name| self.index(name)) {
+            Some(Some(index)) => Some(index),
+            Some(None) => {
+                return Response::Error { message: format!("no service named '{}'", name.unwrap()) };
+            }
+            None => None,
+        };
+
+        let message = match (request, index) {
+            (Request::Status { .. }, index) => {
+                let services = match index {
+                    Some(index) => vec![self.status(index, now)],
+                    None => (0..self.services.len()).map(|index| self.status(index, now)).collect(),
+                };
+                return Response::Status { services };
+            }
+            (Request::Start { service }, Some(index)) => {
+                if self.services[index].wanted && self.services[index].running() {
+                    format!("{} is already running", service)
+                } else {
+                    self.want(index);
+                    format!("Starting {}", service)
+                }
+            }
+            (Request::Stop { service }, Some(index)) => {
+                if !self.services[index].wanted && self.services[index].pid().is_none() {
+                    format!("{} is not running", service)
+                } else {
+                    self.unwant(index);
+                    format!("Stopping {}", service)
+                }
+            }
+            (Request::Restart { service }, Some(index)) => {
+                self.want(index);
+                if self.services[index].running() {
+                    self.log.init(Priority::Notice, &format!("restarting {}", service));
+                    self.stop(index, now);
+                }
+                format!("Restarting {}", service)
+            }
+            _ => unreachable!(),
+        };
+        Response::Done { message }
+    }
+
+    // Collect every child that has exited
+    pub fn reap(&mut self, now: Instant) {
+        loop {
+            let mut status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                break;
+            }
+            // Anything else was an orphan, and is now gone
+            if let Some(index) = self.services.iter().position(|service| service.pid() == Some(pid as u32)) {
+                self.exited(index, status, now);
+            }
+        }
+    }
+
+    // One pass over the services in start order
+    pub fn step(&mut self, now: Instant) {
+        for index in 0..self.services.len() {
+            let service = &self.services[index];
+            match service.state {
+                State::Waiting if self.blockers(index).is_empty() => self.spawn(index, now),
+                State::Running { .. } if !service.wanted && !self.held(index) => self.stop(index, now),
+                State::Running { .. } if service.wanted => {
+                    let lost = service.unit.requires.iter().find(|name| {
+                        self.index(name).is_some_and(|requirement| !self.services[requirement].running())
+                    });
+                    if let Some(lost) = lost {
+                        let message = format!("stopping {}: {} is no longer running", service.unit.name, lost);
+                        self.log.init(Priority::Warning, &message);
+                        self.stop(index, now);
+                    }
+                }
+                State::Stopping { pid, deadline, killed: false } if now >= deadline => {
+                    let message = format!("{} did not stop in time; killing it", service.unit.name);
+                    self.log.init(Priority::Warning, &message);
+                    signal(pid, libc::SIGKILL);
+                    self.services[index].state = State::Stopping { pid, deadline, killed: true };
+                }
+                State::Backoff { until } if now >= until => {
+                    self.services[index].state = State::Waiting;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Stop every service, dependents first, and wait until all have
+    pub fn shutdown(&mut self) {
+        for index in 0..self.services.len() {
+            self.unwant(index);
+        }
+        loop {
+            let now = Instant::now();
+            self.reap(now);
+            self.step(now);
+            if self.services.iter().all(|service| service.pid().is_none()) {
+                break;
+            }
+            thread::sleep(SHUTDOWN_POLL);
+        }
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.services.iter().position(|service| service.unit.name == name)
+    }
+
+    fn want(&mut self, index: usize) {
+        let service = &mut self.services[index];
+        service.wanted = true;
+        if matches!(service.state, State::Stopped | State::Failed(_)) {
+            service.state = State::Waiting;
+            service.failures = 0;
+        }
+        for requirement in service.unit.requires.clone() {
+            if let Some(requirement) = self.index(&requirement) {
+                if !self.services[requirement].wanted {
+                    self.want(requirement);
+                }
+            }
+        }
+    }
+
+    fn unwant(&mut self, index: usize) {
+        let service = &mut self.services[index];
+        service.wanted = false;
+        if matches!(service.state, State::Waiting | State::Backoff { .. }) {
+            service.state = State::Stopped;
+        }
+        let name = service.unit.name.clone();
+        for dependent in 0..self.services.len() {
+            if self.services[dependent].wanted && self.services[dependent].unit.requires.contains(&name) {
+                self.unwant(dependent);
+            }
+        }
+    }
+
+    // What a waiting service is waiting on: requirements not yet running,
+    // and services it comes after that are still on their way up
+    fn blockers(&self, index: usize) -> Vec<&str> {
+        let unit = &self.services[index].unit;
+        let required = unit.requires.iter().filter(|name| {
+            self.index(name).is_some_and(|requirement| !self.services[requirement].running())
+        });
+        let ordered = unit.after.iter().filter(|name| {
+            self.index(name).is_some_and(|other| self.services[other].wanted && !self.services[other].running())
+        });
+        required.chain(ordered).map(String::as_str).collect()
+    }
+
+    // Whether a service that is being stopped must wait for others that
+    // depend on it to stop first
+    fn held(&self, index: usize) -> bool {
+        let name = &self.services[index].unit.name;
+        self.services.iter().any(|service| {
+            !service.wanted && service.pid().is_some() && service.unit.dependencies().any(|dependency| dependency == name)
+        })
+    }
+
+    fn spawn(&mut self, index: usize, now: Instant) {
+        let unit = &self.services[index].unit;
+        let mut command = Command::new(&unit.exec[0]);
+        command
+            .args(&unit.exec[1..])
+            .envs(&unit.environment)
+            .current_dir(&unit.working_directory)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Its own process group, so stopping it reaches what it starts
+            .process_group(0);
+
+        match command.spawn() {
+            Ok(mut child) => {
+                let pid = child.id();
+                let name = unit.name.clone();
+                if let Some(stdout) = child.stdout.take() {
+                    forward(stdout, Arc::clone(&self.log), name.clone(), pid, Priority::Info);
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    forward(stderr, Arc::clone(&self.log), name.clone(), pid, Priority::Err);
+                }
+                // The child is reaped by pid, not through `child`
+                self.log.init(Priority::Info, &format!("started {} (pid {})", name, pid));
+                self.services[index].state = State::Running { pid, since: now };
+            }
+            Err(e) => self.ended(index, now, format!("could not be started: {}", e), false, Duration::ZERO),
+        }
+    }
+
+    fn stop(&mut self, index: usize, now: Instant) {
+        let service = &mut self.services[index];
+        if let State::Running { pid, .. } = service.state {
+            signal(pid, libc::SIGTERM);
+            let deadline = now + service.unit.stop_timeout();
+            service.state = State::Stopping { pid, deadline, killed: false };
+        }
+    }
+
+    fn exited(&mut self, index: usize, status: libc::c_int, now: Instant) {
+        let (description, success) = describe(status);
+        let service = &mut self.services[index];
+        match service.state {
+            State::Stopping { .. } => {
+                self.log.init(Priority::Info, &format!("stopped {}", service.unit.name));
+                service.last_exit = Some(description);
+                // Still wanted when it was restarted or lost a requirement
+                service.state = if service.wanted { State::Waiting } else { State::Stopped };
+            }
+            State::Running { since, .. } => {
+                let ran = now.saturating_duration_since(since);
+                self.ended(index, now, description, success, ran);
+            }
+            _ => {}
+        }
+    }
+
+    // Decide what follows a service ending on its own, after running for
+    // `ran`
+    fn ended(&mut self, index: usize, now: Instant, description: String, success: bool, ran: Duration) {
+        let service = &mut self.services[index];
+        let name = service.unit.name.clone();
+        service.last_exit = Some(description.clone());
+        if ran >= STABLE_AFTER {
+            service.failures = 0;
+        }
+
+        let restart = service.wanted
+            && match service.unit.restart {
+                Restart::Always => true,
+                Restart::OnFailure => !success,
+                Restart::Never => false,
+            };
+        if !restart {
+            service.wanted = false;
+            if success {
+                service.state = State::Stopped;
+                self.log.init(Priority::Info, &format!("{} {}", name, description));
+            } else {
+                service.state = State::Failed(description.clone());
+                self.log.init(Priority::Err, &format!("{} {}", name, description));
+            }
+            return;
+        }
+
+        service.failures += 1;
+        let limit = service.unit.restart_limit;
+        if limit > 0 && service.failures > limit {
+            service.wanted = false;
+            let reason = format!("{}; gave up after {} quick restarts", description, limit);
+            self.log.init(Priority::Err, &format!("{} {}", name, reason));
+            service.state = State::Failed(reason);
+            return;
+        }
+
+        let delay = service.unit.restart_delay(service.failures);
+        service.restarts += 1;
+        service.state = State::Backoff { until: now + delay };
+        let message = format!("{} {}; restarting in {:.1}s", name, description, delay.as_secs_f64());
+        self.log.init(Priority::Warning, &message);
+    }
+
+    fn status(&self, index: usize, now: Instant) -> ServiceStatus {
+        let service = &self.services[index];
+        let (state, detail) = match &service.state {
+            State::Stopped => ("stopped", None),
+            State::Waiting => ("waiting", Some(format!("waiting for {}", self.blockers(index).join(", ")))),
+            State::Running { .. } => ("running", None),
+            State::Stopping { .. } => ("stopping", None),
+            State::Backoff { until } => {
+                let left = until.saturating_duration_since(now).as_secs_f64();
+                ("restarting", Some(format!("restarting in {:.1}s", left)))
+            }
+            State::Failed(reason) => ("failed", Some(reason.clone())),
+        };
+        let uptime_seconds = match service.state {
+            State::Running { since, .. } => Some(now.saturating_duration_since(since).as_secs()),
+            _ => None,
+        };
+
+        ServiceStatus {
+            name: service.unit.name.clone(),
+            description: service.unit.description.clone(),
+            enabled: service.unit.enabled,
+            state: state.to_string(),
+            detail,
+            pid: service.pid(),
+            uptime_seconds,
+            restarts: service.restarts,
+            last_exit: service.last_exit.clone(),
+        }
+    }
+}
+
+// Signal a service's process group, or the process alone if it has left
+// the group
+fn signal(pid: u32, signal: libc::c_int) {
+    let pid = pid as libc::pid_t;
+    unsafe {
+        if libc::kill(-pid, signal) != 0 {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+// How a child ended, and whether that was success
+fn describe(status: libc::c_int) -> (String, bool) {
+    if libc::WIFEXITED(status) {
+        match libc::WEXITSTATUS(status) {
+            0 => ("exited".to_string(), true),
+            code => (format!("exited with status {}", code), false),
+        }
+    } else if libc::WIFSIGNALED(status) {
+        let number = libc::WTERMSIG(status);
+        let name = match number {
+            libc::SIGHUP => " (SIGHUP)",
+            libc::SIGINT => " (SIGINT)",
+            libc::SIGABRT => " (SIGABRT)",
+            libc::SIGKILL => " (SIGKILL)",
+            libc::SIGSEGV => " (SIGSEGV)",
+            libc::SIGTERM => " (SIGTERM)",
+            _ => "",
+        };
+        (format!("was killed by signal {}{}", number, name), false)
+    } else {
+        (format!("ended with wait status {}", status), false)
+    }
+}
+
+// Send each line a service writes to the log, from a thread of its own
+// that ends when the service closes the stream
+fn forward(stream: impl Read + Send + 'static, log: Arc<SystemLog>, tag: String, pid: u32, priority: Priority) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.by_ref().take(MAX_OUTPUT_LINE).read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let text = String::from_utf8_lossy(&line);
+                    log.write(&tag, pid, priority, text.trim_end_matches(['\n', '\r']));
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor(units: &[(&str, &str)]) -> Supervisor {
+        let units = units.iter().map(|(name, text)| Unit::parse(name, text).unwrap()).collect();
+        Supervisor::new(units, Arc::new(SystemLog::new()))
+    }
+
+    // Pretend the service was started at `since`, without running anything
+    fn run(supervisor: &mut Supervisor, index: usize, pid: u32, since: Instant) {
+        supervisor.services[index].wanted = true;
+        supervisor.services[index].state = State::Running { pid, since };
+    }
+
+    fn state(supervisor: &Supervisor, index: usize, now: Instant) -> (String, Option<String>) {
+        let status = supervisor.status(index, now);
+        (status.state, status.detail)
+    }
+
+    // Wait statuses as waitpid reports them
+    fn exit_status(code: i32) -> libc::c_int {
+        code << 8
+    }
+
+    fn killed_by(signal: libc::c_int) -> libc::c_int {
+        signal
+    }
+
+    const FAILING: &str = "exec = [\"/bin/false\"]\nrestart_delay_seconds = 1\nrestart_delay_max_seconds = 3\nrestart_limit = 3";
+
+    #[test]
+    fn describes_exits() {
+        assert_eq!(describe(exit_status(0)), ("exited".to_string(), true));
+        assert_eq!(describe(exit_status(3)), ("exited with status 3".to_string(), false));
+        assert_eq!(describe(killed_by(libc::SIGKILL)), ("was killed by signal 9 (SIGKILL)".to_string(), false));
+    }
+
+    #[test]
+    fn backs_off_longer_after_each_quick_failure_then_gives_up() {
+        let mut supervisor = supervisor(&[("svc", FAILING)]);
+        let mut now = Instant::now();
+        let mut delays = Vec::new();
+        for pid in 1..=3 {
+            run(&mut supervisor, 0, pid, now);
+            now += Duration::from_secs(1);
+            supervisor.exited(0, exit_status(1), now);
+            let State::Backoff { until } = supervisor.services[0].state else {
+                panic!("not backing off after failure {}", pid);
+            };
+            delays.push(until - now);
+            now = until;
+        }
+        assert_eq!(delays, [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(3)]);
+        assert_eq!(supervisor.services[0].restarts, 3);
+
+        run(&mut supervisor, 0, 4, now);
+        supervisor.exited(0, exit_status(1), now + Duration::from_secs(1));
+        let (state, detail) = state(&supervisor, 0, now);
+        assert_eq!(state, "failed");
+        assert_eq!(detail.unwrap(), "exited with status 1; gave up after 3 quick restarts");
+        assert!(!supervisor.services[0].wanted);
+    }
+
+    #[test]
+    fn a_long_run_resets_the_backoff() {
+        let mut supervisor = supervisor(&[("svc", FAILING)]);
+        let mut now = Instant::now();
+        for pid in 1..=2 {
+            run(&mut supervisor, 0, pid, now);
+            now += Duration::from_secs(1);
+            supervisor.exited(0, exit_status(1), now);
+        }
+        assert_eq!(supervisor.services[0].failures, 2);
+
+        run(&mut supervisor, 0, 3, now);
+        now += STABLE_AFTER;
+        supervisor.exited(0, killed_by(libc::SIGSEGV), now);
+        let State::Backoff { until } = supervisor.services[0].state else {
+            panic!("not backing off");
+        };
+        assert_eq!(until - now, Duration::from_secs(1));
+        assert_eq!(supervisor.services[0].failures, 1);
+    }
+
+    #[test]
+    fn restarts_as_the_policy_says() {
+        let mut supervisor = supervisor(&[
+            ("always", "exec = [\"/bin/true\"]\nrestart = \"always\""),
+            ("on-failure", "exec = [\"/bin/true\"]"),
+            ("never", "exec = [\"/bin/false\"]\nrestart = \"never\""),
+        ]);
+        let now = Instant::now();
+        for index in 0..3 {
+            run(&mut supervisor, index, index as u32 + 1, now);
+        }
+        supervisor.exited(0, exit_status(0), now);
+        supervisor.exited(1, exit_status(0), now);
+        supervisor.exited(2, exit_status(1), now);
+        assert_eq!(state(&supervisor, 0, now).0, "restarting");
+        assert_eq!(state(&supervisor, 1, now).0, "stopped");
+        assert_eq!(state(&supervisor, 2, now), ("failed".to_string(), Some("exited with status 1".to_string())));
+    }
+
+    #[test]
+    fn waits_out_the_backoff_before_starting_again() {
+        let mut supervisor = supervisor(&[("svc", FAILING)]);
+        let now = Instant::now();
+        run(&mut supervisor, 0, 1, now);
+        supervisor.exited(0, exit_status(1), now);
+
+        supervisor.step(now + Duration::from_millis(900));
+        assert_eq!(state(&supervisor, 0, now).0, "restarting");
+        supervisor.step(now + Duration::from_secs(1));
+        assert_eq!(state(&supervisor, 0, now).0, "waiting");
+    }
+
+    #[test]
+    fn a_stop_that_was_asked_for_is_not_a_failure() {
+        let mut supervisor = supervisor(&[("svc", FAILING)]);
+        let now = Instant::now();
+        run(&mut supervisor, 0, 1, now);
+        supervisor.unwant(0);
+        supervisor.services[0].state = State::Stopping { pid: 1, deadline: now, killed: false };
+        supervisor.exited(0, killed_by(libc::SIGTERM), now);
+        assert_eq!(state(&supervisor, 0, now).0, "stopped");
+        assert_eq!(supervisor.services[0].restarts, 0);
+        assert_eq!(supervisor.services[0].last_exit.as_deref(), Some("was killed by signal 15 (SIGTERM)"));
+    }
+
+    #[test]
+    fn starting_a_service_wants_its_requirements_and_waits_for_them() {
+        let mut supervisor = supervisor(&[
+            ("syslogd", "exec = [\"/bin/syslogd\"]\nenabled = false"),
+            ("netd", "exec = [\"/bin/netd\"]\nenabled = false\nrequires = [\"syslogd\"]"),
+        ]);
+        let now = Instant::now();
+        let response = supervisor.handle(Request::Start { service: "netd".to_string() }, now);
+        assert!(matches!(response, Response::Done { message } if message == "Starting netd"));
+        assert!(supervisor.services[0].wanted);
+        assert_eq!(state(&supervisor, 1, now), ("waiting".to_string(), Some("waiting for syslogd".to_string())));
+
+        // Losing the requirement stops the dependent too
+        supervisor.unwant(0);
+        assert!(!supervisor.services[1].wanted);
+        assert_eq!(state(&supervisor, 1, now).0, "stopped");
+    }
+}
@@ -0,0 +1,427 @@
+//! Service unit files
+//!
+//! Each service is described by a TOML file in the unit directory, named
+//! for the service, so `/etc/init/netd.toml` describes `netd`:
+//!
+//! ```toml
+//! description = "Network daemon"
+//! exec = ["/sbin/netd", "--foreground"]
+//! environment = { RUST_LOG = "info" }
+//! requires = ["syslogd"]
+//! after = ["klogd"]
+//! restart = "on-failure"
+//! ```
+//!
+//! | Key                         | Default        | Meaning                                              |
+//! |-----------------------------|----------------|------------------------------------------------------|
+//! | `exec`                      | required       | Program and arguments, run directly, not by a shell  |
+//! | `description`               | empty          | Shown by `svcctl status`                             |
+//! | `environment`               | none           | Variables added to init's environment                |
+//! | `working_directory`         | `/`            | Directory the program starts in                      |
+//! | `enabled`                   | `true`         | Start at boot                                        |
+//! | `requires`                  | none           | Services that must be running first                  |
+//! | `after`                     | none           | Services to start first when both are starting       |
+//! | `restart`                   | `"on-failure"` | `"always"`, `"on-failure"` or `"never"`              |
+//! | `restart_delay_seconds`     | `0.5`          | Wait before the first restart, doubled each time     |
+//! | `restart_delay_max_seconds` | `30`           | Longest wait between restarts                        |
+//! | `restart_limit`             | `5`            | Quick restarts in a row before giving up; 0, never   |
+//! | `stop_timeout_seconds`      | `5`            | Wait after SIGTERM before SIGKILL                    |
+//!
+//! A service that requires another is started only while that one runs.
+//! If the other stops or dies, the service is stopped too, and started
+//! again once the other is back. Starting a service starts what it
+//! requires; stopping one stops what requires it. `after` only orders
+//! starts, and names of services that do not exist are ignored there.
+//!
+//! A service that requires one that does not exist, or that is in a
+//! dependency cycle, is not loaded.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const UNIT_DIR: &str = "/etc/init";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    Always,
+    #[default]
+    OnFailure,
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Unit {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub exec: Vec<String>,
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+    #[serde(default = "default_working_directory")]
+    pub working_directory: PathBuf,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub requires: Vec<String>,
+    #[serde(default)]
+    pub after: Vec<String>,
+    #[serde(default)]
+    pub restart: Restart,
+    #[serde(default = "default_restart_delay")]
+    pub restart_delay_seconds: f64,
+    #[serde(default = "default_restart_delay_max")]
+    pub restart_delay_max_seconds: f64,
+    #[serde(default = "default_restart_limit")]
+    pub restart_limit: u32,
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout_seconds: f64,
+}
+
+fn default_working_directory() -> PathBuf {
+    PathBuf::from("/")
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_restart_delay() -> f64 {
+    0.5
+}
+
+fn default_restart_delay_max() -> f64 {
+    30.0
+}
+
+fn default_restart_limit() -> u32 {
+    5
+}
+
+fn default_stop_timeout() -> f64 {
+    5.0
+}
+
+impl Unit {
+    pub fn parse(name: &str, text: &str) -> Result<Unit, Box<dyn Error>> {
+        if !valid_name(name) {
+            return Err(format!("'{}' is not a valid service name", name).into());
+        }
+        let mut unit: Unit = toml::from_str(text).map_err(|e| {
+            // On one line, for the log
+            match e.span() {
+                Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, e.message()),
+                None => e.message().to_string(),
+            }
+        })?;
+        unit.name = name.to_string();
+
+        if unit.exec.first().is_none_or(|program| program.is_empty()) {
+            return Err("exec must name a program".into());
+        }
+        for (key, seconds) in [
+            ("restart_delay_seconds", unit.restart_delay_seconds),
+            ("restart_delay_max_seconds", unit.restart_delay_max_seconds),
+            ("stop_timeout_seconds", unit.stop_timeout_seconds),
+        ] {
+            if !(0.0..=86400.0).contains(&seconds) {
+                return Err(format!("{} must be between 0 and 86400", key).into());
+            }
+        }
+        if let Some(dependency) = unit.requires.iter().chain(&unit.after).find(|dependency| **dependency == unit.name) {
+            return Err(format!("'{}' cannot depend on itself", dependency).into());
+        }
+        Ok(unit)
+    }
+
+    // Wait before restart number `attempt`, counting from 1
+    pub fn restart_delay(&self, attempt: u32) -> Duration {
+        let factor = 2f64.powi(attempt.saturating_sub(1).min(32) as i32);
+        Duration::from_secs_f64((self.restart_delay_seconds * factor).min(self.restart_delay_max_seconds))
+    }
+
+    pub fn stop_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.stop_timeout_seconds)
+    }
+
+    // Services this one waits for, by requirement or ordering
+    pub fn dependencies(&self) -> impl Iterator<Item = &String> {
+        self.requires.iter().chain(&self.after)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'))
+}
+
+// Every unit in `dir`, in start order, with a line for each file or unit
+// left out and why
+pub fn load(dir: &Path) -> (Vec<Unit>, Vec<String>) {
+    let mut problems = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            problems.push(format!("cannot read {}: {}", dir.display(), e));
+            return (Vec::new(), problems);
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+        .collect();
+    paths.sort();
+
+    let mut units = BTreeMap::new();
+    for path in paths {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let loaded = fs::read_to_string(&path)
+            .map_err(|e| e.into())
+            .and_then(|text| Unit::parse(&name, &text));
+        match loaded {
+            Ok(unit) => {
+                units.insert(name, unit);
+            }
+            Err(e) => problems.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    let (order, dropped) = order(units);
+    problems.extend(dropped);
+    (order, problems)
+}
+
+// Put units in start order, each after what it depends on. Units that
+// require one missing, or are caught in a cycle, are dropped with a reason.
+pub fn order(mut units: BTreeMap<String, Unit>) -> (Vec<Unit>, Vec<String>) {
+    let mut problems = Vec::new();
+
+    // Dropping a unit may leave others without a requirement in turn
+    loop {
+        let missing: Vec<(String, String)> = units
+            .values()
+            .filter_map(|unit| {
+                let requirement = unit.requires.iter().find(|requirement| !units.contains_key(*requirement))?;
+                Some((unit.name.clone(), requirement.clone()))
+            })
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        for (name, requirement) in missing {
+            units.remove(&name);
+            problems.push(format!("{}: requires '{}', which is not loaded", name, requirement));
+        }
+    }
+
+    // Kahn's algorithm, taking ready units by name so the order is stable
+    let mut waiting_on: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut dependents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for unit in units.values() {
+        let dependencies: BTreeSet<&String> = unit.dependencies().filter(|name| units.contains_key(*name)).collect();
+        waiting_on.insert(&unit.name, dependencies.len());
+        for dependency in dependencies {
+            dependents.entry(dependency).or_default().push(&unit.name);
+        }
+    }
+    let mut ready: BTreeSet<&str> = waiting_on.iter().filter(|(_, count)| **count == 0).map(|(name, _)| *name).collect();
+    let mut sorted = Vec::new();
+    while let Some(name) = ready.pop_first() {
+        sorted.push(name.to_string());
+        for dependent in dependents.get(name).into_iter().flatten() {
+            let count = waiting_on.get_mut(dependent).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(dependent);
+            }
+        }
+    }
+
+    let cyclic: Vec<String> = waiting_on
+        .iter()
+        .filter(|(name, _)| !sorted.iter().any(|sorted| sorted == *name))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    for name in &cyclic {
+        problems.push(format!("{}: is in, or depends on, a dependency cycle", name));
+    }
+
+    let order = sorted.into_iter().filter_map(|name| units.remove(&name)).collect();
+    (order, problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(name: &str, text: &str) -> Unit {
+        Unit::parse(name, text).unwrap()
+    }
+
+    fn error(name: &str, text: &str) -> String {
+        Unit::parse(name, text).unwrap_err().to_string()
+    }
+
+    fn units(list: &[(&str, &str)]) -> BTreeMap<String, Unit> {
+        list.iter().map(|(name, text)| (name.to_string(), unit(name, text))).collect()
+    }
+
+    fn names(units: &[Unit]) -> Vec<&str> {
+        units.iter().map(|unit| unit.name.as_str()).collect()
+    }
+
+    #[test]
+    fn fills_in_the_defaults() {
+        let unit = unit("netd", r#"exec = ["/sbin/netd"]"#);
+        assert_eq!(unit.name, "netd");
+        assert_eq!(unit.description, "");
+        assert_eq!(unit.working_directory, PathBuf::from("/"));
+        assert!(unit.enabled);
+        assert_eq!(unit.restart, Restart::OnFailure);
+        assert_eq!(unit.restart_delay_seconds, 0.5);
+        assert_eq!(unit.restart_delay_max_seconds, 30.0);
+        assert_eq!(unit.restart_limit, 5);
+        assert_eq!(unit.stop_timeout(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reads_every_key() {
+        let unit = unit(
+            "netd",
+            r#"
+            description = "Network daemon"
+            exec = ["/sbin/netd", "--foreground"]
+            environment = { RUST_LOG = "info" }
+            working_directory = "/var/lib/netd"
+            enabled = false
+            requires = ["syslogd"]
+            after = ["klogd"]
+            restart = "always"
+            restart_delay_seconds = 1
+            restart_delay_max_seconds = 8.0
+            restart_limit = 0
+            stop_timeout_seconds = 2.5
+            "#,
+        );
+        assert_eq!(unit.description, "Network daemon");
+        assert_eq!(unit.exec, ["/sbin/netd", "--foreground"]);
+        assert_eq!(unit.environment["RUST_LOG"], "info");
+        assert_eq!(unit.working_directory, PathBuf::from("/var/lib/netd"));
+        assert!(!unit.enabled);
+        assert_eq!(unit.restart, Restart::Always);
+        assert_eq!(unit.restart_limit, 0);
+        assert_eq!(unit.stop_timeout(), Duration::from_millis(2500));
+        assert_eq!(unit.dependencies().collect::<Vec<_>>(), ["syslogd", "klogd"]);
+    }
+
+    #[test]
+    fn rejects_bad_units() {
+        assert_eq!(error("", r#"exec = ["/bin/true"]"#), "'' is not a valid service name");
+        assert_eq!(error(".hidden", r#"exec = ["/bin/true"]"#), "'.hidden' is not a valid service name");
+        assert_eq!(error("a/b", r#"exec = ["/bin/true"]"#), "'a/b' is not a valid service name");
+        assert_eq!(error("svc", "exec = []"), "exec must name a program");
+        assert_eq!(error("svc", r#"exec = [""]"#), "exec must name a program");
+        assert_eq!(
+            error("svc", "exec = [\"/bin/true\"]\nstop_timeout_seconds = -1"),
+            "stop_timeout_seconds must be between 0 and 86400"
+        );
+        assert_eq!(
+            error("svc", "exec = [\"/bin/true\"]\nafter = [\"svc\"]"),
+            "'svc' cannot depend on itself"
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_a_syntax_error() {
+        let message = error("svc", "exec = [\"/bin/true\"]\nrestart = \"sometimes\"");
+        assert!(message.starts_with("line 2: "), "{}", message);
+        let message = error("svc", "exec = [\"/bin/true\"]\n\nshell = true");
+        assert!(message.starts_with("line 3: "), "{}", message);
+    }
+
+    #[test]
+    fn doubles_the_restart_delay_up_to_the_limit() {
+        let unit = unit("svc", "exec = [\"/bin/true\"]\nrestart_delay_seconds = 1\nrestart_delay_max_seconds = 10");
+        let delays: Vec<u64> = (1..=6).map(|attempt| unit.restart_delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(unit.restart_delay(0), Duration::from_secs(1));
+        assert_eq!(unit.restart_delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn orders_units_after_their_dependencies() {
+        let (order, problems) = order(units(&[
+            ("app", "exec = [\"/bin/app\"]\nrequires = [\"netd\"]"),
+            ("klogd", "exec = [\"/bin/klogd\"]"),
+            ("netd", "exec = [\"/bin/netd\"]\nrequires = [\"syslogd\"]\nafter = [\"klogd\", \"missing\"]"),
+            ("syslogd", "exec = [\"/bin/syslogd\"]"),
+        ]));
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(names(&order), ["klogd", "syslogd", "netd", "app"]);
+    }
+
+    #[test]
+    fn drops_units_whose_requirements_are_missing() {
+        let (order, problems) = order(units(&[
+            ("app", "exec = [\"/bin/app\"]\nrequires = [\"netd\"]"),
+            ("netd", "exec = [\"/bin/netd\"]\nrequires = [\"dhcpd\"]"),
+            ("syslogd", "exec = [\"/bin/syslogd\"]"),
+        ]));
+        assert_eq!(names(&order), ["syslogd"]);
+        assert_eq!(
+            problems,
+            [
+                "netd: requires 'dhcpd', which is not loaded",
+                "app: requires 'netd', which is not loaded",
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_units_in_or_behind_a_cycle() {
+        let (order, problems) = order(units(&[
+            ("a", "exec = [\"/bin/a\"]\nrequires = [\"b\"]"),
+            ("b", "exec = [\"/bin/b\"]\nafter = [\"a\"]"),
+            ("c", "exec = [\"/bin/c\"]\nrequires = [\"a\"]"),
+            ("d", "exec = [\"/bin/d\"]"),
+        ]));
+        assert_eq!(names(&order), ["d"]);
+        assert_eq!(
+            problems,
+            [
+                "a: is in, or depends on, a dependency cycle",
+                "b: is in, or depends on, a dependency cycle",
+                "c: is in, or depends on, a dependency cycle",
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_the_toml_files_in_a_directory() {
+        let dir = std::env::temp_dir().join(format!("init-units-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("netd.toml"), "exec = [\"/bin/netd\"]\nrequires = [\"syslogd\"]").unwrap();
+        fs::write(dir.join("syslogd.toml"), "exec = [\"/bin/syslogd\"]").unwrap();
+        fs::write(dir.join("broken.toml"), "exec = 1").unwrap();
+        fs::write(dir.join("README"), "not a unit").unwrap();
+
+        let (units, problems) = load(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names(&units), ["syslogd", "netd"]);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("broken.toml: line 1: "), "{}", problems[0]);
+
+        let (units, problems) = load(&dir);
+        assert!(units.is_empty());
+        assert!(problems[0].starts_with("cannot read "), "{}", problems[0]);
+    }
+}
@@ -0,0 +1,88 @@
+/*
+ * RustOS system calls for C programs
+ *
+ * Implemented by libsys built with the `c-abi` feature. The POSIX-named
+ * functions return -1 and set errno on failure, as POSIX says; those
+ * prefixed rustos_ have no POSIX counterpart. See userspace/libsys/src/c.rs.
+ */
+
+#ifndef RUSTOS_H
+#define RUSTOS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef long ssize_t;
+typedef int pid_t;
+typedef int64_t off_t;
+
+int *__errno_location(void);
+#define errno (*__errno_location())
+
+/* open() flags, as on Linux */
+#define O_RDONLY  0x0
+#define O_WRONLY  0x1
+#define O_RDWR    0x2
+#define O_CREAT   0x40
+#define O_APPEND  0x400
+#define O_CLOEXEC 0x80000
+
+#define PROT_NONE  0x0
+#define PROT_READ  0x1
+#define PROT_WRITE 0x2
+#define PROT_EXEC  0x4
+
+#define MAP_SHARED    0x01
+#define MAP_PRIVATE   0x02
+#define MAP_FIXED     0x10
+#define MAP_ANONYMOUS 0x20
+#define MAP_FAILED    ((void *)-1)
+
+#define MS_ASYNC      0x1
+#define MS_INVALIDATE 0x2
+#define MS_SYNC       0x4
+
+ssize_t read(int fd, void *buf, size_t count);
+ssize_t write(int fd, const void *buf, size_t count);
+int open(const char *path, int flags);
+int close(int fd);
+int ioctl(int fd, unsigned long request, void *arg);
+
+void *mmap(void *addr, size_t length, int prot, int flags, int fd, off_t offset);
+int munmap(void *addr, size_t length);
+int msync(void *addr, size_t length, int flags);
+int brk(void *addr);
+
+void exit(int status) __attribute__((noreturn));
+void _exit(int status) __attribute__((noreturn));
+pid_t getpid(void);
+pid_t fork(void);
+pid_t vfork(void);
+int execv(const char *path, char *const argv[]);
+pid_t waitpid(pid_t pid, int *status, int options);
+int kill(pid_t pid, int signal);
+
+unsigned int sleep(unsigned int seconds);
+int usleep(unsigned int microseconds);
+
+int gethostname(char *name, size_t length);
+int sethostname(const char *name, size_t length);
+
+/* Start the program at path with the NULL-terminated argv, returning its pid */
+pid_t rustos_spawn(const char *path, char *const argv[]);
+/* Seconds since boot */
+uint64_t rustos_uptime(void);
+/* TCP: descriptors read, written and closed as files, which never block */
+int rustos_listen(uint16_t port);
+int rustos_connect(uint32_t address, uint16_t port);
+int rustos_accept(int fd);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
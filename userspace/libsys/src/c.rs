@@ -0,0 +1,193 @@
+//! The C ABI, with the `c-abi` feature
+//!
+//! POSIX-named functions behave as their POSIX counterparts do, within
+//! what the kernel supports: they return -1 and set `errno` on failure.
+//! Calls with no POSIX counterpart are prefixed `rustos_`. The functions
+//! are declared in `include/rustos.h`.
+//!
+//! Built for RustOS, the library also supplies the panic handler, which C
+//! programs linking it have no other way to provide.
+
+// The pointers are the C caller's to get right, as with any C library
+#![allow(clippy::missing_safety_doc)]
+
+use crate::error::check;
+use crate::raw::{self, nr};
+use crate::{process, thread};
+use core::ffi::{c_char, c_int, c_uint, c_ulong, c_void};
+use core::sync::atomic::{AtomicI32, Ordering};
+use core::time::Duration;
+
+// One for the process, shared by its threads, as there is no thread-local
+// storage to give each its own
+static ERRNO: AtomicI32 = AtomicI32::new(0);
+
+// The result as C wants it: the value, or -1 with errno set
+fn ret(result: isize) -> isize {
+    match check(result) {
+        Ok(value) => value as isize,
+        Err(errno) => {
+            ERRNO.store(errno.0, Ordering::Relaxed);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn __errno_location() -> *mut c_int {
+    ERRNO.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize {
+    ret(raw::syscall3(nr::READ, fd as usize, buf as usize, count))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: usize) -> isize {
+    ret(raw::syscall3(nr::WRITE, fd as usize, buf as usize, count))
+}
+
+// No mode: files are created with the file system's default permissions
+#[no_mangle]
+pub unsafe extern "C" fn open(path: *const c_char, flags: c_int) -> c_int {
+    ret(raw::syscall2(nr::OPEN, path as usize, flags as usize)) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn close(fd: c_int) -> c_int {
+    ret(unsafe { raw::syscall1(nr::CLOSE, fd as usize) }) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ioctl(fd: c_int, request: c_ulong, arg: *mut c_void) -> c_int {
+    ret(raw::syscall3(nr::IOCTL, fd as usize, request as usize, arg as usize)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmap(addr: *mut c_void, length: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void {
+    let start = ret(raw::syscall6(nr::MMAP, addr as usize, length, prot as usize, flags as usize, fd as usize, offset as usize));
+    // MAP_FAILED is -1 too
+    start as *mut c_void
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn munmap(addr: *mut c_void, length: usize) -> c_int {
+    ret(raw::syscall2(nr::MUNMAP, addr as usize, length)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn msync(addr: *mut c_void, length: usize, flags: c_int) -> c_int {
+    ret(raw::syscall3(nr::MSYNC, addr as usize, length, flags as usize)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn brk(addr: *mut c_void) -> c_int {
+    ret(raw::syscall1(nr::BRK, addr as usize)).min(0) as c_int
+}
+
+// No atexit handlers or stdio buffers to flush, so the same as _exit
+#[no_mangle]
+pub extern "C" fn exit(status: c_int) -> ! {
+    process::exit(status)
+}
+
+#[no_mangle]
+pub extern "C" fn _exit(status: c_int) -> ! {
+    process::exit(status)
+}
+
+#[no_mangle]
+pub extern "C" fn getpid() -> c_int {
+    process::getpid() as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn fork() -> c_int {
+    ret(unsafe { raw::syscall0(nr::FORK) }) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vfork() -> c_int {
+    ret(raw::syscall0(nr::VFORK)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    ret(raw::syscall2(nr::EXEC, path as usize, argv as usize)) as c_int
+}
+
+/// Start the program at `path` with the NULL-terminated `argv`, returning
+/// the child's pid
+#[no_mangle]
+pub unsafe extern "C" fn rustos_spawn(path: *const c_char, argv: *const *const c_char) -> c_int {
+    ret(raw::syscall2(nr::SPAWN, path as usize, argv as usize)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn waitpid(pid: c_int, status: *mut c_int, _options: c_int) -> c_int {
+    let result = ret(raw::syscall1(nr::WAIT, pid as usize));
+    if result >= 0 && !status.is_null() {
+        *status = result as c_int;
+        return pid;
+    }
+    result as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn kill(pid: c_int, signal: c_int) -> c_int {
+    ret(unsafe { raw::syscall2(nr::KILL, pid as usize, signal as usize) }) as c_int
+}
+
+#[no_mangle]
+pub extern "C" fn sleep(seconds: c_uint) -> c_uint {
+    thread::sleep(Duration::from_secs(seconds as u64));
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn usleep(microseconds: c_uint) -> c_int {
+    thread::sleep(Duration::from_micros(microseconds as u64));
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gethostname(name: *mut c_char, length: usize) -> c_int {
+    ret(raw::syscall2(nr::GETHOSTNAME, name as usize, length)) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sethostname(name: *const c_char, length: usize) -> c_int {
+    ret(raw::syscall2(nr::SETHOSTNAME, name as usize, length)) as c_int
+}
+
+/// Seconds since boot
+#[no_mangle]
+pub extern "C" fn rustos_uptime() -> u64 {
+    unsafe { raw::syscall0(nr::GETTIME) as u64 }
+}
+
+/// A descriptor listening for TCP connections on `port`
+#[no_mangle]
+pub extern "C" fn rustos_listen(port: u16) -> c_int {
+    ret(unsafe { raw::syscall1(nr::LISTEN, port as usize) }) as c_int
+}
+
+/// A descriptor connecting to `port` at `address`, in host byte order
+#[no_mangle]
+pub extern "C" fn rustos_connect(address: u32, port: u16) -> c_int {
+    ret(unsafe { raw::syscall2(nr::CONNECT, address as usize, port as usize) }) as c_int
+}
+
+/// A connection waiting on the listening descriptor `fd`
+#[no_mangle]
+pub extern "C" fn rustos_accept(fd: c_int) -> c_int {
+    ret(unsafe { raw::syscall1(nr::ACCEPT, fd as usize) }) as c_int
+}
+
+#[cfg(target_os = "rustos")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crate::eprintln!("{}", info);
+    process::exit(101)
+}
@@ -0,0 +1,143 @@
+//! Errors from the kernel
+//!
+//! A failed system call returns its errno negated. The numbers are Linux's,
+//! and these are the ones the kernel uses.
+
+use core::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Errno(pub i32);
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+macro_rules! errnos {
+    ($($name:ident = $value:expr, $description:expr;)*) => {
+        impl Errno {
+            $(pub const $name: Errno = Errno($value);)*
+
+            /// The constant's name, as "ENOENT"
+            pub fn name(self) -> Option<&'static str> {
+                match self {
+                    $(Errno::$name => Some(stringify!($name)),)*
+                    _ => None,
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(Errno::$name => $description,)*
+                    _ => "Unknown error",
+                }
+            }
+        }
+    };
+}
+
+errnos! {
+    EPERM = 1, "Operation not permitted";
+    ENOENT = 2, "No such file or directory";
+    ESRCH = 3, "No such process";
+    EINTR = 4, "Interrupted system call";
+    EIO = 5, "Input/output error";
+    ENXIO = 6, "No such device or address";
+    E2BIG = 7, "Argument list too long";
+    ENOEXEC = 8, "Exec format error";
+    EBADF = 9, "Bad file descriptor";
+    ECHILD = 10, "No child processes";
+    EAGAIN = 11, "Resource temporarily unavailable";
+    ENOMEM = 12, "Cannot allocate memory";
+    EACCES = 13, "Permission denied";
+    EFAULT = 14, "Bad address";
+    ENOTBLK = 15, "Block device required";
+    EBUSY = 16, "Device or resource busy";
+    EEXIST = 17, "File exists";
+    EXDEV = 18, "Invalid cross-device link";
+    ENODEV = 19, "No such device";
+    ENOTDIR = 20, "Not a directory";
+    EISDIR = 21, "Is a directory";
+    EINVAL = 22, "Invalid argument";
+    ENFILE = 23, "Too many open files in system";
+    EMFILE = 24, "Too many open files";
+    ENOTTY = 25, "Inappropriate ioctl for device";
+    ETXTBSY = 26, "Text file busy";
+    EFBIG = 27, "File too large";
+    ENOSPC = 28, "No space left on device";
+    ESPIPE = 29, "Illegal seek";
+    EROFS = 30, "Read-only file system";
+    EMLINK = 31, "Too many links";
+    EPIPE = 32, "Broken pipe";
+    EDOM = 33, "Numerical argument out of domain";
+    ERANGE = 34, "Numerical result out of range";
+    ENAMETOOLONG = 36, "File name too long";
+    ENOSYS = 38, "Function not implemented";
+    ENOTSOCK = 88, "Socket operation on non-socket";
+    EADDRINUSE = 98, "Address already in use";
+    ECONNRESET = 104, "Connection reset by peer";
+    ENOTCONN = 107, "Transport endpoint is not connected";
+}
+
+// As Linux, the top 4095 values are errors and the rest results, so
+// addresses and masks with the top bit set still come through
+const MAX_ERRNO: isize = 4095;
+
+/// A system call's result as a value or the error
+pub fn check(ret: isize) -> Result<usize> {
+    if (-MAX_ERRNO..0).contains(&ret) {
+        Err(Errno(-ret as i32))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Errno({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (os error {})", self.description(), self.0)
+    }
+}
+
+impl core::error::Error for Errno {}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn splits_results_from_errors() {
+        assert_eq!(check(0), Ok(0));
+        assert_eq!(check(4096), Ok(4096));
+        assert_eq!(check(-1), Err(Errno::EPERM));
+        assert_eq!(check(-4095), Err(Errno(4095)));
+        // Addresses in the upper half are results
+        assert_eq!(check(-4096), Ok(-4096isize as usize));
+        assert_eq!(check(isize::MIN), Ok(isize::MIN as usize));
+    }
+
+    #[test]
+    fn names_and_describes_errors() {
+        assert_eq!(Errno::ENOENT.name(), Some("ENOENT"));
+        assert_eq!(Errno(2), Errno::ENOENT);
+        assert_eq!(Errno(35).name(), None);
+        assert_eq!(Errno::EAGAIN.description(), "Resource temporarily unavailable");
+        assert_eq!(Errno(35).description(), "Unknown error");
+    }
+
+    #[test]
+    fn formats_errors() {
+        assert_eq!(format!("{:?}", Errno::EBADF), "EBADF");
+        assert_eq!(format!("{:?}", Errno(999)), "Errno(999)");
+        assert_eq!(format!("{}", Errno::EACCES), "Permission denied (os error 13)");
+        assert_eq!(format!("{}", Errno(999)), "Unknown error (os error 999)");
+    }
+}
@@ -0,0 +1,174 @@
+//! Files and descriptors
+//!
+//! Paths are NUL-terminated, as the kernel reads them, so they are taken as
+//! `&CStr`: `c"/etc/hostname"`. Descriptors 0, 1 and 2 are the console.
+//! There is no seek or stat call yet; a file is read and written from its
+//! start, or appended to with `O_APPEND`.
+
+use crate::error::{check, Errno, Result};
+use crate::raw::{self, nr};
+use core::ffi::CStr;
+use core::fmt;
+
+// open() flags, as on Linux
+pub const O_RDONLY: usize = 0x0;
+pub const O_WRONLY: usize = 0x1;
+pub const O_RDWR: usize = 0x2;
+pub const O_CREAT: usize = 0x40;
+pub const O_APPEND: usize = 0x400;
+pub const O_CLOEXEC: usize = 0x80000;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+pub fn open(path: &CStr, flags: usize) -> Result<usize> {
+    check(unsafe { raw::syscall2(nr::OPEN, path.as_ptr() as usize, flags) })
+}
+
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize> {
+    check(unsafe { raw::syscall3(nr::READ, fd, buf.as_mut_ptr() as usize, buf.len()) })
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize> {
+    check(unsafe { raw::syscall3(nr::WRITE, fd, buf.as_ptr() as usize, buf.len()) })
+}
+
+// Writes until all of `buf` is out, since one write may take only part
+pub fn write_all(fd: usize, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match write(fd, buf)? {
+            0 => return Err(Errno::EIO),
+            written => buf = &buf[written..],
+        }
+    }
+    Ok(())
+}
+
+pub fn close(fd: usize) -> Result<()> {
+    check(unsafe { raw::syscall1(nr::CLOSE, fd) }).map(|_| ())
+}
+
+/// # Safety
+///
+/// `arg` must be what the device expects for `request`, often a pointer to
+/// a structure the kernel reads or writes.
+pub unsafe fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize> {
+    check(raw::syscall3(nr::IOCTL, fd, request, arg))
+}
+
+/// An open file, closed when dropped
+#[derive(Debug)]
+pub struct File {
+    fd: usize,
+}
+
+impl File {
+    pub fn open(path: &CStr) -> Result<File> {
+        File::open_with(path, O_RDONLY)
+    }
+
+    /// Open `path` for writing, creating it if it is not there
+    pub fn create(path: &CStr) -> Result<File> {
+        File::open_with(path, O_WRONLY | O_CREAT)
+    }
+
+    pub fn open_with(path: &CStr, flags: usize) -> Result<File> {
+        open(path, flags).map(|fd| File { fd })
+    }
+
+    /// # Safety
+    ///
+    /// `fd` must be open, and owned by nothing else that will close it.
+    pub unsafe fn from_raw_fd(fd: usize) -> File {
+        File { fd }
+    }
+
+    pub fn as_raw_fd(&self) -> usize {
+        self.fd
+    }
+
+    pub fn into_raw_fd(self) -> usize {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        read(self.fd, buf)
+    }
+
+    // Reads until `buf` is full or the file ends, returning how much it got
+    pub fn read_full(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read(&mut buf[filled..])? {
+                0 => break,
+                count => filled += count,
+            }
+        }
+        Ok(filled)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        write(self.fd, buf)
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        write_all(self.fd, buf)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+
+impl fmt::Write for File {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// The console, for `write!` and the print macros
+pub struct Stdout;
+
+pub struct Stderr;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(STDOUT, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+impl fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_all(STDERR, s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+        let _ = core::fmt::Write::write_fmt(&mut $crate::fs::Stdout, format_args!($($arg)*));
+    }};
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\n") };
+    ($($arg:tt)*) => {{
+        let _ = core::fmt::Write::write_fmt(&mut $crate::fs::Stdout, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => {{
+        let _ = core::fmt::Write::write_str(&mut $crate::fs::Stderr, "\n");
+    }};
+    ($($arg:tt)*) => {{
+        let _ = core::fmt::Write::write_fmt(&mut $crate::fs::Stderr, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
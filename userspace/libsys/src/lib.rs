@@ -0,0 +1,58 @@
+//! libsys: system calls for programs running on RustOS
+//!
+//! Safe wrappers for the kernel's system calls, which fail with an
+//! [`Errno`], and with the `c-abi` feature the C functions C programs call
+//! for them. It needs only `core`, so it builds for RustOS itself:
+//!
+//! | Module      | Calls                                                      |
+//! |-------------|------------------------------------------------------------|
+//! | [`fs`]      | open, read, write, close, ioctl; the console               |
+//! | [`mem`]     | mmap, munmap, msync, brk; a page allocator                 |
+//! | [`process`] | exit, getpid, fork, vfork, exec, spawn, wait, kill         |
+//! | [`thread`]  | spawn, join, exit, sleep, yield, CPU affinity              |
+//! | [`net`]     | TCP listen, accept and connect                             |
+//! | [`time`]    | time since boot, CPU times                                 |
+//! | [`raw`]     | every system call by number, unchecked                     |
+//!
+//! # Building for RustOS
+//!
+//! `x86_64-unknown-rustos.json` in this directory is the target for user
+//! programs: position-independent static executables, which the kernel's
+//! ELF loader places and relocates. It is not built into rustc, so `core`
+//! and `alloc` are built for it from source, with nightly, and with them
+//! `memcpy` and the other functions a C library would otherwise supply:
+//!
+//! ```text
+//! cargo +nightly build --release \
+//!     --target userspace/libsys/x86_64-unknown-rustos.json \
+//!     -Zbuild-std=core,alloc -Zbuild-std-features=compiler-builtins-mem \
+//!     -Zjson-target-spec
+//! ```
+//!
+//! A `#![no_std]` program depends on libsys, defines `_start`, and ends
+//! with `process::exit`. For a heap it sets [`mem::PageAllocator`] as its
+//! global allocator. A C program includes `include/rustos.h` and links
+//! libsys built as a static library with `c-abi`:
+//!
+//! ```text
+//! cargo +nightly rustc --release --features c-abi --crate-type staticlib \
+//!     --target userspace/libsys/x86_64-unknown-rustos.json \
+//!     -Zbuild-std=core -Zbuild-std-features=compiler-builtins-mem \
+//!     -Zjson-target-spec
+//! ```
+
+#![no_std]
+
+pub mod error;
+pub mod fs;
+pub mod mem;
+pub mod net;
+pub mod process;
+pub mod raw;
+pub mod thread;
+pub mod time;
+
+#[cfg(feature = "c-abi")]
+pub mod c;
+
+pub use error::{Errno, Result};
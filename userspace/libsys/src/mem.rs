@@ -0,0 +1,103 @@
+//! Memory: mappings and the program break
+
+use crate::error::{check, Result};
+use crate::raw::{self, nr};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+pub const MS_ASYNC: usize = 0x1;
+pub const MS_INVALIDATE: usize = 0x2;
+pub const MS_SYNC: usize = 0x4;
+
+/// Map `length` bytes of `fd` from `offset`, or of zeroed memory with
+/// `MAP_ANONYMOUS`, near `addr` or wherever is free when it is null
+///
+/// # Safety
+///
+/// With `MAP_FIXED` the mapping replaces whatever was at `addr`, which must
+/// not be memory the program still uses.
+pub unsafe fn mmap(addr: *mut u8, length: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> Result<*mut u8> {
+    check(raw::syscall6(nr::MMAP, addr as usize, length, prot, flags, fd, offset)).map(|start| start as *mut u8)
+}
+
+/// # Safety
+///
+/// Nothing may use the pages in the range afterwards.
+pub unsafe fn munmap(addr: *mut u8, length: usize) -> Result<()> {
+    check(raw::syscall2(nr::MUNMAP, addr as usize, length)).map(|_| ())
+}
+
+/// Write a shared file mapping's changed pages back to the file, at once
+/// with `MS_SYNC`
+pub fn msync(addr: *mut u8, length: usize, flags: usize) -> Result<()> {
+    check(unsafe { raw::syscall3(nr::MSYNC, addr as usize, length, flags) }).map(|_| ())
+}
+
+/// Move the end of the heap to `end`, returning where it was
+///
+/// # Safety
+///
+/// Moving it down frees what was above, which must be unused.
+pub unsafe fn brk(end: usize) -> Result<usize> {
+    check(raw::syscall1(nr::BRK, end))
+}
+
+/// `length` bytes of fresh zeroed memory, read and write, in whole pages
+pub fn map_anonymous(length: usize) -> Result<*mut u8> {
+    unsafe { mmap(ptr::null_mut(), length, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0) }
+}
+
+/// A global allocator mapping whole pages for each allocation
+///
+/// It wastes most of a page on small allocations, but needs no state and
+/// gives memory back as soon as it is freed. Alignments above a page are
+/// not supported, and fail.
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOCATOR: libsys::mem::PageAllocator = libsys::mem::PageAllocator;
+/// ```
+pub struct PageAllocator;
+
+unsafe impl GlobalAlloc for PageAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() > PAGE_SIZE {
+            return ptr::null_mut();
+        }
+        map_anonymous(layout.size().max(1)).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = munmap(ptr, layout.size().max(1));
+    }
+
+    // Anonymous pages are already zeroed
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Refused before any system call is made
+    #[test]
+    fn refuses_alignments_above_a_page() {
+        let layout = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE * 2).unwrap();
+        assert!(unsafe { PageAllocator.alloc(layout) }.is_null());
+        assert!(unsafe { PageAllocator.alloc_zeroed(layout) }.is_null());
+    }
+}
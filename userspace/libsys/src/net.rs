@@ -0,0 +1,105 @@
+//! TCP over IPv4
+//!
+//! Streams are descriptors, read, written and closed like files. Nothing
+//! blocks: a call that cannot go ahead yet fails with `EAGAIN`, which is an
+//! accept with no connection waiting, a read with nothing received, or a
+//! write before the handshake is done or with the send window shut. The
+//! `_blocking` methods retry those, sleeping between tries. Ports below
+//! 1024 are an administrator's to listen on.
+
+use crate::error::{check, Errno, Result};
+use crate::fs;
+use crate::raw::{self, nr};
+use crate::thread;
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+// Between retries of a call that failed with EAGAIN
+const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+fn retry<T>(mut call: impl FnMut() -> Result<T>) -> Result<T> {
+    loop {
+        match call() {
+            Err(Errno::EAGAIN) => thread::sleep(RETRY_INTERVAL),
+            result => return result,
+        }
+    }
+}
+
+/// A listening port, closed when dropped
+#[derive(Debug)]
+pub struct TcpListener {
+    fd: usize,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener> {
+        check(unsafe { raw::syscall1(nr::LISTEN, port as usize) }).map(|fd| TcpListener { fd })
+    }
+
+    /// A connection waiting on the port
+    pub fn accept(&self) -> Result<TcpStream> {
+        check(unsafe { raw::syscall1(nr::ACCEPT, self.fd) }).map(|fd| TcpStream { fd })
+    }
+
+    pub fn accept_blocking(&self) -> Result<TcpStream> {
+        retry(|| self.accept())
+    }
+
+    pub fn as_raw_fd(&self) -> usize {
+        self.fd
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let _ = fs::close(self.fd);
+    }
+}
+
+/// A connection, closed when dropped
+#[derive(Debug)]
+pub struct TcpStream {
+    fd: usize,
+}
+
+impl TcpStream {
+    /// Start connecting to `port` at `address`. Writes fail with `EAGAIN`
+    /// until the handshake is done.
+    pub fn connect(address: Ipv4Addr, port: u16) -> Result<TcpStream> {
+        check(unsafe { raw::syscall2(nr::CONNECT, u32::from(address) as usize, port as usize) }).map(|fd| TcpStream { fd })
+    }
+
+    /// Received bytes, or 0 once the peer has closed its side
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        fs::read(self.fd, buf)
+    }
+
+    pub fn read_blocking(&mut self, buf: &mut [u8]) -> Result<usize> {
+        retry(|| fs::read(self.fd, buf))
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        fs::write(self.fd, buf)
+    }
+
+    pub fn write_all_blocking(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match retry(|| fs::write(self.fd, buf))? {
+                0 => return Err(Errno::ECONNRESET),
+                written => buf = &buf[written..],
+            }
+        }
+        Ok(())
+    }
+
+    pub fn as_raw_fd(&self) -> usize {
+        self.fd
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let _ = fs::close(self.fd);
+    }
+}
@@ -0,0 +1,117 @@
+//! Processes
+//!
+//! A program's arguments are passed as NUL-terminated strings. The kernel
+//! joins them with spaces into the new process's command line, and takes
+//! at most 256. Waiting for a child to exit is not implemented by the
+//! kernel yet, so `wait` fails with `ENOSYS`.
+
+use crate::error::{check, Errno, Result};
+use crate::raw::{self, nr};
+use core::ffi::{c_char, CStr};
+use core::ptr;
+
+// The kernel's limit on exec and spawn arguments
+pub const MAX_ARGS: usize = 256;
+
+pub const SIGKILL: usize = 9;
+pub const SIGTERM: usize = 15;
+
+/// Which side of a fork the caller is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fork {
+    Child,
+    Parent(u32),
+}
+
+pub fn exit(status: i32) -> ! {
+    unsafe {
+        raw::syscall1(nr::EXIT, status as usize);
+    }
+    // The kernel never returns from exit
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// The caller's pid, as its PID namespace numbers it
+pub fn getpid() -> u32 {
+    unsafe { raw::syscall0(nr::GETPID) as u32 }
+}
+
+/// Copy the calling process
+pub fn fork() -> Result<Fork> {
+    match check(unsafe { raw::syscall0(nr::FORK) })? {
+        0 => Ok(Fork::Child),
+        pid => Ok(Fork::Parent(pid as u32)),
+    }
+}
+
+/// Start a child in the caller's own memory, holding the caller until the
+/// child execs or exits
+///
+/// # Safety
+///
+/// Until then the child may only call `exec` or `exit`: anything else it
+/// does changes the parent's memory and stack under it.
+pub unsafe fn vfork() -> Result<Fork> {
+    match check(raw::syscall0(nr::VFORK))? {
+        0 => Ok(Fork::Child),
+        pid => Ok(Fork::Parent(pid as u32)),
+    }
+}
+
+/// Replace the caller's program with the one at `path`. It returns only on
+/// failure, when the caller carries on with the program it has.
+pub fn exec(path: &CStr, args: &[&CStr]) -> Errno {
+    let argv = match argv(args) {
+        Ok(argv) => argv,
+        Err(e) => return e,
+    };
+    match check(unsafe { raw::syscall2(nr::EXEC, path.as_ptr() as usize, argv.as_ptr() as usize) }) {
+        // Success leaves nothing of the caller to return to
+        Ok(_) => Errno::EIO,
+        Err(e) => e,
+    }
+}
+
+/// Start the program at `path` as a child that inherits the caller's open
+/// descriptors, returning its pid
+pub fn spawn(path: &CStr, args: &[&CStr]) -> Result<u32> {
+    let argv = argv(args)?;
+    check(unsafe { raw::syscall2(nr::SPAWN, path.as_ptr() as usize, argv.as_ptr() as usize) }).map(|pid| pid as u32)
+}
+
+// `args` as the NULL-terminated array of pointers the kernel reads
+fn argv(args: &[&CStr]) -> Result<[*const c_char; MAX_ARGS + 1]> {
+    if args.len() > MAX_ARGS {
+        return Err(Errno::E2BIG);
+    }
+    let mut argv = [ptr::null(); MAX_ARGS + 1];
+    for (slot, arg) in argv.iter_mut().zip(args) {
+        *slot = arg.as_ptr();
+    }
+    Ok(argv)
+}
+
+/// Wait for child `pid` to exit, returning its status
+pub fn wait(pid: u32) -> Result<i32> {
+    check(unsafe { raw::syscall1(nr::WAIT, pid as usize) }).map(|status| status as i32)
+}
+
+/// Send `signal` to process `pid`. The kernel ends the process whatever
+/// the signal.
+pub fn kill(pid: u32, signal: usize) -> Result<()> {
+    check(unsafe { raw::syscall2(nr::KILL, pid as usize, signal) }).map(|_| ())
+}
+
+pub fn set_hostname(name: &str) -> Result<()> {
+    check(unsafe { raw::syscall2(nr::SETHOSTNAME, name.as_ptr() as usize, name.len()) }).map(|_| ())
+}
+
+/// Write the hostname into `buf`, returning it. It fails with
+/// `ENAMETOOLONG` if `buf` has no room for it and a NUL after.
+pub fn hostname(buf: &mut [u8]) -> Result<&str> {
+    check(unsafe { raw::syscall2(nr::GETHOSTNAME, buf.as_mut_ptr() as usize, buf.len()) })?;
+    let name = CStr::from_bytes_until_nul(buf).map_err(|_| Errno::EINVAL)?;
+    name.to_str().map_err(|_| Errno::EINVAL)
+}
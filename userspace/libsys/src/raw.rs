@@ -0,0 +1,175 @@
+//! Raw system calls
+//!
+//! The number goes in rax and up to six arguments in rdi, rsi, rdx, r10, r8
+//! and r9, as `kernel/src/syscall/mod.rs` reads them. The result comes back
+//! in rax: a value, or an errno negated. `syscall` itself overwrites rcx
+//! and r11 with the return address and flags.
+//!
+//! Every call here is unsafe in the same way: the kernel reads and writes
+//! whatever memory the arguments point at, and some calls change the
+//! process under the caller.
+
+#![allow(clippy::missing_safety_doc)]
+
+use core::arch::asm;
+
+/// System call numbers, as `dispatch_syscall` in the kernel matches them
+pub mod nr {
+    pub const EXIT: usize = 0;
+    pub const READ: usize = 1;
+    pub const WRITE: usize = 2;
+    pub const OPEN: usize = 3;
+    pub const CLOSE: usize = 4;
+    pub const FORK: usize = 5;
+    pub const EXEC: usize = 6;
+    pub const WAIT: usize = 7;
+    pub const KILL: usize = 8;
+    pub const GETPID: usize = 9;
+    pub const BRK: usize = 10;
+    pub const MMAP: usize = 11;
+    pub const MUNMAP: usize = 12;
+    pub const SLEEP: usize = 13;
+    pub const GETTIME: usize = 14;
+    pub const IOCTL: usize = 15;
+    pub const UNSHARE: usize = 16;
+    pub const SETNS: usize = 17;
+    pub const SETHOSTNAME: usize = 18;
+    pub const GETHOSTNAME: usize = 19;
+    pub const MOUNT: usize = 20;
+    pub const GROUP_CREATE: usize = 21;
+    pub const GROUP_SET: usize = 22;
+    pub const GROUP_JOIN: usize = 23;
+    pub const GROUP_DESTROY: usize = 24;
+    pub const SYSLOG: usize = 25;
+    pub const MSYNC: usize = 26;
+    pub const VFORK: usize = 27;
+    pub const SPAWN: usize = 28;
+    pub const SCHED_SETAFFINITY: usize = 29;
+    pub const SCHED_GETAFFINITY: usize = 30;
+    pub const CPU_SET_ONLINE: usize = 31;
+    pub const CPU_TIMES: usize = 32;
+    pub const IOPRIO_SET: usize = 33;
+    pub const IOPRIO_GET: usize = 34;
+    pub const LISTEN: usize = 35;
+    pub const CONNECT: usize = 36;
+    pub const ACCEPT: usize = 37;
+    pub const IO_URING_SETUP: usize = 38;
+    pub const IO_URING_ENTER: usize = 39;
+    pub const CET_CONTROL: usize = 40;
+    pub const SHUTDOWN: usize = 41;
+    pub const REBOOT: usize = 42;
+    pub const THREAD_CREATE: usize = 43;
+    pub const THREAD_EXIT: usize = 44;
+    pub const THREAD_JOIN: usize = 45;
+    pub const CREATE_WINDOW: usize = 100;
+    pub const DESTROY_WINDOW: usize = 101;
+    pub const DRAW_WINDOW: usize = 102;
+    pub const HANDLE_EVENT: usize = 103;
+    pub const GET_SCREEN_INFO: usize = 104;
+}
+
+#[inline(always)]
+pub unsafe fn syscall0(number: usize) -> isize {
+    let ret;
+    asm!("syscall", inlateout("rax") number as isize => ret, lateout("rcx") _, lateout("r11") _, options(nostack));
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(number: usize, arg1: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(number: usize, arg1: usize, arg2: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(number: usize, arg1: usize, arg2: usize, arg3: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(number: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall5(number: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        in("r8") arg5,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(number: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize, arg6: usize) -> isize {
+    let ret;
+    asm!(
+        "syscall",
+        inlateout("rax") number as isize => ret,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        in("r8") arg5,
+        in("r9") arg6,
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    ret
+}
@@ -0,0 +1,128 @@
+//! Threads
+//!
+//! [`spawn`] starts a thread in the caller's process, which shares its
+//! memory and descriptors, on a stack of its own. The thread runs a
+//! function given a `usize`, often a pointer to what it works on, and ends
+//! when the function returns or calls [`exit`]. The function's result is
+//! what [`JoinHandle::join`] returns. A panic, or `process::exit`, in any
+//! thread ends the whole process.
+//!
+//! ```ignore
+//! fn count(limit: usize) -> i32 {
+//!     (0..limit).filter(|n| n % 7 == 0).count() as i32
+//! }
+//!
+//! let thread = thread::spawn(count, 1000)?;
+//! assert_eq!(thread.join()?, 143);
+//! ```
+
+use crate::error::{check, Result};
+use crate::mem;
+use crate::raw::{self, nr};
+use core::time::Duration;
+
+/// The stack each spawned thread gets, filled as it is touched
+pub const STACK_SIZE: usize = 256 * 1024;
+
+// What a new thread runs, at the top of its stack
+#[repr(C, align(16))]
+struct Start {
+    main: fn(usize) -> i32,
+    arg: usize,
+}
+
+/// A thread started by [`spawn`]
+#[derive(Debug)]
+#[must_use = "a thread that is not joined keeps its stack"]
+pub struct JoinHandle {
+    tid: u32,
+    stack: *mut u8,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> u32 {
+        self.tid
+    }
+
+    /// Wait for the thread to end, returning its status
+    pub fn join(self) -> Result<i32> {
+        let status = check(unsafe { raw::syscall1(nr::THREAD_JOIN, self.tid as usize) })?;
+        // Nothing runs on it any more
+        let _ = unsafe { mem::munmap(self.stack, STACK_SIZE) };
+        Ok(status as u32 as i32)
+    }
+}
+
+/// Start a thread running `main(arg)`
+pub fn spawn(main: fn(usize) -> i32, arg: usize) -> Result<JoinHandle> {
+    let stack = mem::map_anonymous(STACK_SIZE)?;
+    let start = unsafe {
+        let start = stack.add(STACK_SIZE).cast::<Start>().sub(1);
+        start.write(Start { main, arg });
+        start
+    };
+    // As if `thread_start` had been called, with its return address pushed
+    let stack_pointer = start as usize - 8;
+    let entry = thread_start as extern "C" fn(*const Start) -> ! as usize;
+    match check(unsafe { raw::syscall3(nr::THREAD_CREATE, entry, stack_pointer, start as usize) }) {
+        Ok(tid) => Ok(JoinHandle { tid: tid as u32, stack }),
+        Err(errno) => {
+            let _ = unsafe { mem::munmap(stack, STACK_SIZE) };
+            Err(errno)
+        }
+    }
+}
+
+extern "C" fn thread_start(start: *const Start) -> ! {
+    let Start { main, arg } = unsafe { start.read() };
+    exit(main(arg))
+}
+
+/// End the calling thread with `status`; in the thread the process started
+/// with, this ends the process
+pub fn exit(status: i32) -> ! {
+    unsafe {
+        raw::syscall1(nr::THREAD_EXIT, status as usize);
+    }
+    // The kernel never returns from thread_exit
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Sleep for at least `duration`, to the millisecond
+pub fn sleep(duration: Duration) {
+    let milliseconds = duration.as_millis().min(usize::MAX as u128) as usize;
+    unsafe {
+        raw::syscall1(nr::SLEEP, milliseconds);
+    }
+}
+
+/// Give up the rest of the time slice
+pub fn yield_now() {
+    sleep(Duration::ZERO);
+}
+
+/// Let thread `tid`, 0 for the caller, run only on the CPUs set in `mask`
+pub fn set_affinity(tid: u32, mask: u64) -> Result<()> {
+    check(unsafe { raw::syscall2(nr::SCHED_SETAFFINITY, tid as usize, mask as usize) }).map(|_| ())
+}
+
+/// The CPUs thread `tid`, 0 for the caller, may run on, as a mask
+pub fn affinity(tid: u32) -> Result<u64> {
+    check(unsafe { raw::syscall1(nr::SCHED_GETAFFINITY, tid as usize) }).map(|mask| mask as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::{align_of, size_of};
+
+    // `spawn` puts the record at the top of the stack and the stack pointer
+    // a return address below it, so the record must fill 16 bytes exactly
+    #[test]
+    fn start_record_keeps_the_stack_aligned() {
+        assert_eq!(size_of::<Start>(), 16);
+        assert_eq!(align_of::<Start>(), 16);
+    }
+}
@@ -0,0 +1,28 @@
+//! Time
+//!
+//! The kernel gives the time since boot, in whole seconds. Finer and wall
+//! clock time are not available to programs yet.
+
+use crate::error::{check, Result};
+use crate::raw::{self, nr};
+use core::time::Duration;
+
+pub fn uptime() -> Duration {
+    Duration::from_secs(unsafe { raw::syscall0(nr::GETTIME) } as u64)
+}
+
+/// Nanoseconds CPU `cpu` has spent in each state since boot
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub user_ns: u64,
+    pub kernel_ns: u64,
+    pub irq_ns: u64,
+    pub idle_ns: u64,
+}
+
+pub fn cpu_times(cpu: u32) -> Result<CpuTimes> {
+    let mut times = CpuTimes::default();
+    check(unsafe { raw::syscall2(nr::CPU_TIMES, cpu as usize, &mut times as *mut CpuTimes as usize) })?;
+    Ok(times)
+}
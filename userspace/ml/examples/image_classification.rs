@@ -14,6 +14,7 @@ use kernel::ml::{
 
 use crate::models::zoo::{ModelZoo, ModelBenchmark};
 use crate::serving::{ModelServer, ServerConfig, PredictRequest, TensorData};
+use crate::serving::onnx::OnnxModel;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("AI/ML Framework Example: Image Classification");
@@ -176,8 +177,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Box::new(DummyModel)
     )?;
     
+    // Serve the exported ONNX graph on the CPU backend when it is present
+    let onnx_path = Path::new("models/resnet50.onnx");
+    if onnx_path.exists() {
+        server.register_model(
+            "resnet50-onnx".to_string(),
+            "v1".to_string(),
+            Box::new(OnnxModel::load(onnx_path)?)
+        )?;
+    }
+    
     println!("✓ Model server configured");
     println!("  Endpoint: http://0.0.0.0:8080/predict");
+    println!("  gRPC:     0.0.0.0:8081 (open inference protocol)");
     println!("  Models: resnet50:v1");
     
     // Example prediction request
@@ -0,0 +1,402 @@
+// Authentication, model access control and per-client rate limiting,
+// shared by the HTTP and gRPC front ends.
+//
+// Clients present an API key in the X-API-Key header or a token as
+// `Authorization: Bearer <token>`. Each client may be limited to a number of
+// requests per second with a token bucket; without authentication, clients
+// are told apart by address.
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const AUTHORIZATION_HEADER: &str = "authorization";
+
+// Buckets tracked before idle, refilled ones are forgotten, and then the
+// least recently seen
+const MAX_BUCKETS: usize = 10_000;
+// Longest wait a rate-limited client is told to make
+const MAX_RETRY_AFTER: f64 = 3600.0;
+
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+    pub clients: Vec<ClientConfig>,
+    // Names of the clients allowed to use each model. Models not listed are
+    // open to every authenticated client.
+    pub model_acl: HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Default)]
+pub struct ClientConfig {
+    pub name: String,
+    pub api_keys: Vec<String>,
+    pub bearer_tokens: Vec<String>,
+    // May use the /admin endpoints
+    pub admin: bool,
+    // Overrides ServerConfig::rate_limit for this client
+    pub rate_limit: Option<RateLimit>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    // Requests that may be made at once after a quiet spell
+    pub burst: u32,
+}
+
+// Who made a request, once admitted
+#[derive(Clone, Debug)]
+pub struct Caller {
+    // The client's name, or None when authentication is off
+    pub client: Option<String>,
+    pub admin: bool,
+}
+
+// Why a request was turned away before reaching a handler
+#[derive(Debug)]
+pub enum Denied {
+    MissingCredentials,
+    InvalidCredentials,
+    // How long until the client may try again
+    RateLimited(Duration),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Denied::MissingCredentials => write!(f, "Authentication required"),
+            Denied::InvalidCredentials => write!(f, "Invalid credentials"),
+            Denied::RateLimited(_) => write!(f, "Rate limit exceeded"),
+        }
+    }
+}
+
+impl Denied {
+    // Whole seconds to send in a Retry-After header, if any
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Denied::RateLimited(wait) => Some(wait.as_secs_f64().ceil().max(1.0) as u64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scheme {
+    ApiKey,
+    Bearer,
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+    // Last request charged to it, for eviction
+    seen: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst.max(1)),
+            updated: now,
+            seen: now,
+        }
+    }
+    
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.requests_per_second).min(f64::from(self.limit.burst.max(1)));
+        self.updated = now;
+    }
+    
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        self.seen = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = ((1.0 - self.tokens) / self.limit.requests_per_second).min(MAX_RETRY_AFTER);
+        Err(Duration::from_secs_f64(wait))
+    }
+    
+    fn full(&self) -> bool {
+        self.tokens >= f64::from(self.limit.burst.max(1))
+    }
+}
+
+pub struct AccessControl {
+    auth: Option<AuthConfig>,
+    // Every credential, with the index of its client
+    credentials: Vec<(Scheme, String, usize)>,
+    rate_limit: Option<RateLimit>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl AccessControl {
+    pub fn new(auth: Option<AuthConfig>, rate_limit: Option<RateLimit>) -> Self {
+        let mut credentials = Vec::new();
+        for (index, client) in auth.iter().flat_map(|auth| auth.clients.iter()).enumerate() {
+            credentials.extend(client.api_keys.iter().map(|key| (Scheme::ApiKey, key.clone(), index)));
+            credentials.extend(client.bearer_tokens.iter().map(|token| (Scheme::Bearer, token.clone(), index)));
+        }
+        credentials.retain(|(_, secret, _)| !secret.is_empty());
+        
+        Self {
+            auth,
+            credentials,
+            rate_limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    // Authenticate a request from its X-API-Key and Authorization headers,
+    // then charge it to its client's rate limit
+    pub fn admit(&self, api_key: Option<&str>, authorization: Option<&str>, peer: Option<IpAddr>, now: Instant) -> Result<Caller, Denied> {
+        let (caller, limit, bucket) = match &self.auth {
+            None => {
+                let caller = Caller { client: None, admin: true };
+                let bucket = peer.map_or_else(|| "address:unknown".to_string(), |peer| format!("address:{}", peer));
+                (caller, self.rate_limit, bucket)
+            },
+            Some(auth) => {
+                let presented = match (api_key, authorization.and_then(bearer_token)) {
+                    (Some(key), _) => (Scheme::ApiKey, key),
+                    (None, Some(token)) => (Scheme::Bearer, token),
+                    (None, None) => return Err(Denied::MissingCredentials),
+                };
+                let client = &auth.clients[self.lookup(presented).ok_or(Denied::InvalidCredentials)?];
+                let caller = Caller { client: Some(client.name.clone()), admin: client.admin };
+                (caller, client.rate_limit.or(self.rate_limit), format!("client:{}", client.name))
+            },
+        };
+        
+        if let Some(limit) = limit {
+            let mut buckets = self.buckets.lock().unwrap();
+            if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&bucket) {
+                buckets.retain(|_, bucket| {
+                    bucket.refill(now);
+                    !bucket.full()
+                });
+                // Every client is still busy: make room by forgetting the one
+                // heard from longest ago
+                if buckets.len() >= MAX_BUCKETS {
+                    let oldest = buckets.iter().min_by_key(|(_, bucket)| bucket.seen).map(|(key, _)| key.clone());
+                    if let Some(oldest) = oldest {
+                        buckets.remove(&oldest);
+                    }
+                }
+            }
+            buckets.entry(bucket)
+                .or_insert_with(|| Bucket::new(limit, now))
+                .take(now)
+                .map_err(Denied::RateLimited)?;
+        }
+        Ok(caller)
+    }
+    
+    // Whether a caller may use a model
+    pub fn may_use(&self, caller: &Caller, model: &str) -> bool {
+        let Some(auth) = &self.auth else {
+            return true;
+        };
+        match auth.model_acl.get(model) {
+            Some(clients) => caller.client.as_ref().is_some_and(|name| clients.contains(name)),
+            None => true,
+        }
+    }
+    
+    // Every credential is compared, each in constant time, so timing says
+    // nothing about which came close
+    fn lookup(&self, (scheme, secret): (Scheme, &str)) -> Option<usize> {
+        let mut found = None;
+        for (candidate_scheme, candidate, index) in &self.credentials {
+            if constant_time_eq(candidate.as_bytes(), secret.as_bytes()) && *candidate_scheme == scheme {
+                found = Some(*index);
+            }
+        }
+        found
+    }
+}
+
+// The token of a `Bearer` Authorization header
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    
+    const LIMIT: RateLimit = RateLimit { requests_per_second: 2.0, burst: 3 };
+    
+    fn client(name: &str, api_key: &str, token: &str) -> ClientConfig {
+        ClientConfig {
+            name: name.to_string(),
+            api_keys: vec![api_key.to_string()],
+            bearer_tokens: vec![token.to_string()],
+            ..ClientConfig::default()
+        }
+    }
+    
+    fn access(rate_limit: Option<RateLimit>) -> AccessControl {
+        let mut admin = client("ops", "ops-key", "ops-token");
+        admin.admin = true;
+        let mut limited = client("batch", "batch-key", "");
+        limited.rate_limit = Some(RateLimit { requests_per_second: 1.0, burst: 1 });
+        let auth = AuthConfig {
+            clients: vec![client("web", "web-key", "web-token"), admin, limited],
+            model_acl: HashMap::from([("private".to_string(), vec!["ops".to_string()])]),
+        };
+        AccessControl::new(Some(auth), rate_limit)
+    }
+    
+    fn peer(n: u32) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::from(n)))
+    }
+    
+    #[test]
+    fn admits_api_keys_and_bearer_tokens() {
+        let access = access(None);
+        let now = Instant::now();
+        let caller = access.admit(Some("web-key"), None, None, now).unwrap();
+        assert_eq!((caller.client.as_deref(), caller.admin), (Some("web"), false));
+        let caller = access.admit(None, Some("Bearer ops-token"), None, now).unwrap();
+        assert_eq!((caller.client.as_deref(), caller.admin), (Some("ops"), true));
+        assert!(access.admit(None, Some("  bearer   web-token "), None, now).is_ok());
+        
+        assert!(matches!(access.admit(None, None, None, now), Err(Denied::MissingCredentials)));
+        assert!(matches!(access.admit(None, Some("Basic d2ViOmtleQ=="), None, now), Err(Denied::MissingCredentials)));
+        assert!(matches!(access.admit(None, Some("Bearer "), None, now), Err(Denied::MissingCredentials)));
+        assert!(matches!(access.admit(Some("web-ke"), None, None, now), Err(Denied::InvalidCredentials)));
+        // A credential only counts under its own scheme
+        assert!(matches!(access.admit(Some("web-token"), None, None, now), Err(Denied::InvalidCredentials)));
+        assert!(matches!(access.admit(None, Some("Bearer web-key"), None, now), Err(Denied::InvalidCredentials)));
+        // An empty token configured for a client is never accepted
+        assert!(matches!(access.admit(Some(""), None, None, now), Err(Denied::InvalidCredentials)));
+    }
+    
+    #[test]
+    fn everyone_is_an_admin_without_authentication() {
+        let access = AccessControl::new(None, None);
+        let caller = access.admit(None, None, peer(1), Instant::now()).unwrap();
+        assert_eq!((caller.client.as_deref(), caller.admin), (None, true));
+        assert!(access.may_use(&caller, "private"));
+    }
+    
+    #[test]
+    fn restricts_listed_models_to_their_clients() {
+        let access = access(None);
+        let now = Instant::now();
+        let web = access.admit(Some("web-key"), None, None, now).unwrap();
+        let ops = access.admit(Some("ops-key"), None, None, now).unwrap();
+        assert!(!access.may_use(&web, "private"));
+        assert!(access.may_use(&ops, "private"));
+        assert!(access.may_use(&web, "public"));
+    }
+    
+    #[test]
+    fn limits_each_client_to_its_burst_then_refills() {
+        let access = access(Some(LIMIT));
+        let now = Instant::now();
+        for _ in 0..3 {
+            access.admit(Some("web-key"), None, None, now).unwrap();
+        }
+        let Err(denied) = access.admit(Some("web-key"), None, None, now) else {
+            panic!("fourth request admitted");
+        };
+        assert_eq!(denied.retry_after(), Some(1));
+        assert!(matches!(denied, Denied::RateLimited(wait) if wait == Duration::from_millis(500)));
+        
+        // The same client is charged whichever credential it uses, and other
+        // clients have their own buckets
+        assert!(access.admit(None, Some("Bearer web-token"), None, now).is_err());
+        assert!(access.admit(Some("ops-key"), None, None, now).is_ok());
+        
+        // Two requests a second come back, up to the burst
+        assert!(access.admit(Some("web-key"), None, None, now + Duration::from_millis(500)).is_ok());
+        assert!(access.admit(Some("web-key"), None, None, now + Duration::from_millis(500)).is_err());
+        let later = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            access.admit(Some("web-key"), None, None, later).unwrap();
+        }
+        assert!(access.admit(Some("web-key"), None, None, later).is_err());
+    }
+    
+    #[test]
+    fn client_limits_override_the_default() {
+        let access = access(Some(LIMIT));
+        let now = Instant::now();
+        assert!(access.admit(Some("batch-key"), None, None, now).is_ok());
+        assert!(access.admit(Some("batch-key"), None, None, now).is_err());
+        
+        // Without a default only clients with their own limit are limited
+        let access = self::access(None);
+        for _ in 0..10 {
+            assert!(access.admit(Some("web-key"), None, None, now).is_ok());
+        }
+        assert!(access.admit(Some("batch-key"), None, None, now).is_ok());
+        assert!(access.admit(Some("batch-key"), None, None, now).is_err());
+    }
+    
+    #[test]
+    fn limits_anonymous_clients_by_address() {
+        let access = AccessControl::new(None, Some(RateLimit { requests_per_second: 1.0, burst: 1 }));
+        let now = Instant::now();
+        assert!(access.admit(None, None, peer(1), now).is_ok());
+        assert!(access.admit(None, None, peer(1), now).is_err());
+        assert!(access.admit(None, None, peer(2), now).is_ok());
+        assert!(access.admit(None, None, None, now).is_ok());
+        assert!(access.admit(None, None, None, now).is_err());
+    }
+    
+    #[test]
+    fn caps_retry_after() {
+        let access = AccessControl::new(None, Some(RateLimit { requests_per_second: 1e-9, burst: 0 }));
+        let now = Instant::now();
+        assert!(access.admit(None, None, peer(1), now).is_ok());
+        let denied = access.admit(None, None, peer(1), now).unwrap_err();
+        assert_eq!(denied.retry_after(), Some(MAX_RETRY_AFTER as u64));
+        assert_eq!(Denied::InvalidCredentials.retry_after(), None);
+    }
+    
+    #[test]
+    fn forgets_idle_buckets_then_the_least_recently_seen() {
+        let limit = RateLimit { requests_per_second: 1.0, burst: 1 };
+        let access = AccessControl::new(None, Some(limit));
+        let start = Instant::now();
+        let at = |n: u32| start + Duration::from_micros(u64::from(n));
+        for n in 0..MAX_BUCKETS as u32 {
+            access.admit(None, None, peer(n), at(n)).unwrap();
+        }
+        
+        // All still busy, so the first address is dropped and starts afresh
+        let last = MAX_BUCKETS as u32;
+        access.admit(None, None, peer(last), at(last)).unwrap();
+        assert_eq!(access.buckets.lock().unwrap().len(), MAX_BUCKETS);
+        assert!(!access.buckets.lock().unwrap().contains_key("address:0.0.0.0"));
+        assert!(access.admit(None, None, peer(1), at(last)).is_err());
+        assert!(access.admit(None, None, peer(0), at(last)).is_ok());
+        
+        // Once they have refilled, every idle bucket goes at the next newcomer
+        let idle = start + Duration::from_secs(10);
+        access.admit(None, None, peer(last + 1), idle).unwrap();
+        assert_eq!(access.buckets.lock().unwrap().len(), 1);
+    }
+}
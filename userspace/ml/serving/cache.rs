@@ -0,0 +1,308 @@
+// Response cache for an endpoint, keyed by a hash of the request inputs.
+// Least recently used entries go first once the entry or byte limit is
+// reached, and entries older than the TTL are never served.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use super::{PredictRequest, PredictResponse, TensorData, TensorValues};
+
+struct Entry {
+    response: PredictResponse,
+    size: usize,
+    inserted: Instant,
+    // Position in the recency order
+    tick: u64,
+}
+
+pub struct ResponseCache {
+    entries: HashMap<u128, Entry>,
+    // Keys by last use, least recent first
+    recency: BTreeMap<u64, u128>,
+    next_tick: u64,
+    bytes: usize,
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            next_tick: 0,
+            bytes: 0,
+            ttl,
+            max_entries,
+            max_bytes,
+        }
+    }
+    
+    pub fn get(&mut self, key: u128, now: Instant) -> Option<PredictResponse> {
+        let entry = self.entries.get_mut(&key)?;
+        if now.duration_since(entry.inserted) > self.ttl {
+            self.remove(key);
+            return None;
+        }
+        
+        self.recency.remove(&entry.tick);
+        entry.tick = self.next_tick;
+        self.recency.insert(self.next_tick, key);
+        self.next_tick += 1;
+        Some(entry.response.clone())
+    }
+    
+    pub fn insert(&mut self, key: u128, response: PredictResponse, now: Instant) {
+        let size = response_size(&response);
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+        self.remove(key);
+        while self.entries.len() >= self.max_entries || self.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+            }
+        }
+        
+        self.entries.insert(key, Entry { response, size, inserted: now, tick: self.next_tick });
+        self.recency.insert(self.next_tick, key);
+        self.next_tick += 1;
+        self.bytes += size;
+    }
+    
+    // Change the limits, evicting what no longer fits
+    pub fn configure(&mut self, ttl: Duration, max_entries: usize, max_bytes: usize) {
+        self.ttl = ttl;
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        while self.entries.len() > max_entries || self.bytes > max_bytes {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+            }
+        }
+    }
+    
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+    
+    fn remove(&mut self, key: u128) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.recency.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+// Hash of what decides a prediction: the inputs, in name order, and the
+// request parameters. Two differently seeded 64-bit hashes make collisions
+// too unlikely to matter.
+pub fn cache_key(request: &PredictRequest) -> u128 {
+    let mut inputs: Vec<(&String, &TensorData)> = request.inputs.iter().collect();
+    inputs.sort_by(|a, b| a.0.cmp(b.0));
+    let mut parameters: Vec<(&String, &String)> = request.parameters.iter().flatten().collect();
+    parameters.sort();
+    
+    let hash = |seed: u64| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        for (name, tensor) in &inputs {
+            name.hash(&mut hasher);
+            tensor.shape.hash(&mut hasher);
+            tensor.dtype.hash(&mut hasher);
+            hash_values(&tensor.data, &mut hasher);
+        }
+        parameters.hash(&mut hasher);
+        hasher.finish()
+    };
+    (u128::from(hash(0)) << 64) | u128::from(hash(1))
+}
+
+// Floats hash by value, so 0.0 and -0.0 share an entry
+fn hash_values(values: &TensorValues, hasher: &mut DefaultHasher) {
+    match values {
+        TensorValues::Float32(values) => {
+            0u8.hash(hasher);
+            values.iter().for_each(|&value| (value + 0.0).to_bits().hash(hasher));
+        },
+        TensorValues::Float64(values) => {
+            1u8.hash(hasher);
+            values.iter().for_each(|&value| (value + 0.0).to_bits().hash(hasher));
+        },
+        TensorValues::Int32(values) => (2u8, values).hash(hasher),
+        TensorValues::Int64(values) => (3u8, values).hash(hasher),
+        TensorValues::String(values) => (4u8, values).hash(hasher),
+        TensorValues::Bytes(values) => (5u8, values).hash(hasher),
+    }
+}
+
+// Approximate memory held by a cached response
+fn response_size(response: &PredictResponse) -> usize {
+    let tensors: usize = response.outputs.iter()
+        .map(|(name, tensor)| {
+            let data = match &tensor.data {
+                TensorValues::Float32(values) => values.len() * 4,
+                TensorValues::Float64(values) => values.len() * 8,
+                TensorValues::Int32(values) => values.len() * 4,
+                TensorValues::Int64(values) => values.len() * 8,
+                TensorValues::String(values) => values.iter().map(|value| value.len() + 24).sum(),
+                TensorValues::Bytes(values) => values.iter().map(|value| value.len() + 24).sum(),
+            };
+            name.len() + tensor.dtype.len() + tensor.shape.len() * 8 + data + 128
+        })
+        .sum();
+    tensors + response.id.len() + response.model_name.len() + response.model_version.len() + 256
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn response(id: &str, values: usize) -> PredictResponse {
+        let output = TensorData { shape: vec![values], dtype: "float32".to_string(), data: TensorValues::Float32(vec![0.5; values]) };
+        PredictResponse {
+            id: id.to_string(),
+            model_name: "m".to_string(),
+            model_version: "1".to_string(),
+            outputs: HashMap::from([("y".to_string(), output)]),
+            metadata: None,
+        }
+    }
+    
+    fn request(values: TensorValues, parameters: &[(&str, &str)]) -> PredictRequest {
+        let input = TensorData { shape: vec![2], dtype: "float32".to_string(), data: values };
+        PredictRequest {
+            id: "a".to_string(),
+            model_name: "m".to_string(),
+            model_version: None,
+            inputs: HashMap::from([("x".to_string(), input)]),
+            parameters: (!parameters.is_empty())
+                .then(|| parameters.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()),
+        }
+    }
+    
+    const TTL: Duration = Duration::from_secs(60);
+    
+    fn cached(cache: &mut ResponseCache, key: u128, now: Instant) -> Option<String> {
+        cache.get(key, now).map(|response| response.id)
+    }
+    
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        let now = Instant::now();
+        let mut cache = ResponseCache::new(TTL, 2, usize::MAX);
+        cache.insert(1, response("one", 1), now);
+        cache.insert(2, response("two", 1), now);
+        // Reading one makes two the least recently used
+        assert_eq!(cached(&mut cache, 1, now).as_deref(), Some("one"));
+        cache.insert(3, response("three", 1), now);
+        assert_eq!(cached(&mut cache, 2, now), None);
+        assert_eq!(cached(&mut cache, 1, now).as_deref(), Some("one"));
+        assert_eq!(cached(&mut cache, 3, now).as_deref(), Some("three"));
+    }
+    
+    #[test]
+    fn keeps_within_the_byte_limit() {
+        let now = Instant::now();
+        let size = response_size(&response("aa", 8));
+        let mut cache = ResponseCache::new(TTL, 100, size * 2);
+        cache.insert(1, response("aa", 8), now);
+        cache.insert(2, response("bb", 8), now);
+        assert_eq!(cache.bytes, size * 2);
+        cache.insert(3, response("cc", 8), now);
+        assert_eq!((cache.entries.len(), cache.bytes), (2, size * 2));
+        assert_eq!(cached(&mut cache, 1, now), None);
+        
+        // Replacing an entry does not count it twice
+        cache.insert(3, response("dd", 8), now);
+        assert_eq!((cache.entries.len(), cache.bytes), (2, size * 2));
+        assert_eq!(cached(&mut cache, 3, now).as_deref(), Some("dd"));
+        
+        // A response larger than the whole cache is not kept, and evicts nothing
+        cache.insert(4, response("ee", 1000), now);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cached(&mut cache, 4, now), None);
+        
+        let mut disabled = ResponseCache::new(TTL, 0, usize::MAX);
+        disabled.insert(1, response("aa", 1), now);
+        assert_eq!(cached(&mut disabled, 1, now), None);
+    }
+    
+    #[test]
+    fn expires_entries_after_the_ttl() {
+        let now = Instant::now();
+        let mut cache = ResponseCache::new(TTL, 10, usize::MAX);
+        cache.insert(1, response("one", 1), now);
+        assert_eq!(cached(&mut cache, 1, now + TTL).as_deref(), Some("one"));
+        assert_eq!(cached(&mut cache, 1, now + TTL + Duration::from_millis(1)), None);
+        // An expired entry is dropped on sight
+        assert_eq!((cache.entries.len(), cache.recency.len(), cache.bytes), (0, 0, 0));
+    }
+    
+    #[test]
+    fn shrinks_when_reconfigured() {
+        let now = Instant::now();
+        let mut cache = ResponseCache::new(TTL, 10, usize::MAX);
+        for key in 1..=4 {
+            cache.insert(key, response("r", 1), now);
+        }
+        cache.configure(TTL, 2, usize::MAX);
+        let mut kept: Vec<u128> = cache.entries.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, [3, 4]);
+        
+        cache.clear();
+        assert_eq!((cache.entries.len(), cache.recency.len(), cache.bytes), (0, 0, 0));
+    }
+    
+    #[test]
+    fn keys_on_inputs_and_parameters_only() {
+        let base = cache_key(&request(TensorValues::Float32(vec![1.0, 0.0]), &[]));
+        
+        let mut other_id = request(TensorValues::Float32(vec![1.0, 0.0]), &[]);
+        other_id.id = "b".to_string();
+        assert_eq!(cache_key(&other_id), base);
+        
+        // Zero of either sign is the same input
+        assert_eq!(cache_key(&request(TensorValues::Float32(vec![1.0, -0.0]), &[])), base);
+        
+        assert_ne!(cache_key(&request(TensorValues::Float32(vec![1.0, 1.0]), &[])), base);
+        assert_ne!(cache_key(&request(TensorValues::Float32(vec![1.0, 0.0]), &[("top_k", "5")])), base);
+        assert_ne!(cache_key(&request(TensorValues::Float64(vec![1.0, 0.0]), &[])), base);
+        
+        // The same bits under another type are another input
+        let bits = [1.0f32.to_bits() as i32, 0];
+        assert_ne!(cache_key(&request(TensorValues::Int32(bits.to_vec()), &[])), base);
+        
+        let mut reshaped = request(TensorValues::Float32(vec![1.0, 0.0]), &[]);
+        reshaped.inputs.get_mut("x").unwrap().shape = vec![1, 2];
+        assert_ne!(cache_key(&reshaped), base);
+        
+        // Parameter order does not matter
+        let ab = request(TensorValues::Float32(vec![1.0]), &[("a", "1"), ("b", "2")]);
+        let ba = request(TensorValues::Float32(vec![1.0]), &[("b", "2"), ("a", "1")]);
+        assert_eq!(cache_key(&ab), cache_key(&ba));
+    }
+    
+    #[test]
+    fn keys_inputs_by_name() {
+        let mut two = request(TensorValues::Float32(vec![1.0, 2.0]), &[]);
+        let y = TensorData { shape: vec![1], dtype: "float32".to_string(), data: TensorValues::Float32(vec![3.0]) };
+        two.inputs.insert("y".to_string(), y.clone());
+        let mut swapped = request(TensorValues::Float32(vec![1.0, 2.0]), &[]);
+        swapped.inputs.insert("z".to_string(), y);
+        assert_ne!(cache_key(&two), cache_key(&swapped));
+        assert_eq!(cache_key(&two), cache_key(&two.clone()));
+    }
+}
@@ -0,0 +1,945 @@
+// gRPC front end speaking the open inference protocol (KServe v2), so that
+// Triton, KServe and TF-Serving v2 clients can call the server directly
+
+// Handlers fail with tonic's Status, which is large but what the service
+// has to return
+#![allow(clippy::result_large_err)]
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Instant,
+};
+
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    codec::ProstCodec,
+    codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, UnaryService},
+    transport::{server::TcpConnectInfo, Server},
+    metadata::MetadataMap,
+    Code, Request, Response, Status,
+};
+
+use self::inference::*;
+use super::{model_key, predict, ABTestManager, ModelEndpoint, ModelError, PredictRequest, ServerConfig, ServerError, ServerMetrics, TensorData, TensorValues};
+use super::auth::{self, AccessControl, Caller, Denied};
+use super::logging::{self, AccessLog, REQUEST_ID_HEADER};
+use super::repository::compare_versions;
+
+// Messages of grpc_predict_v2.proto, package `inference`
+pub mod inference {
+    use std::collections::HashMap;
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerLiveRequest {}
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerLiveResponse {
+        #[prost(bool, tag = "1")]
+        pub live: bool,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerReadyRequest {}
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerReadyResponse {
+        #[prost(bool, tag = "1")]
+        pub ready: bool,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelReadyRequest {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub version: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelReadyResponse {
+        #[prost(bool, tag = "1")]
+        pub ready: bool,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerMetadataRequest {}
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ServerMetadataResponse {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub version: String,
+        #[prost(string, repeated, tag = "3")]
+        pub extensions: Vec<String>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelMetadataRequest {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub version: String,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelMetadataResponse {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, repeated, tag = "2")]
+        pub versions: Vec<String>,
+        #[prost(string, tag = "3")]
+        pub platform: String,
+        #[prost(message, repeated, tag = "4")]
+        pub inputs: Vec<TensorMetadata>,
+        #[prost(message, repeated, tag = "5")]
+        pub outputs: Vec<TensorMetadata>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TensorMetadata {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub datatype: String,
+        // -1 for a dimension of any size
+        #[prost(int64, repeated, tag = "3")]
+        pub shape: Vec<i64>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelInferRequest {
+        #[prost(string, tag = "1")]
+        pub model_name: String,
+        #[prost(string, tag = "2")]
+        pub model_version: String,
+        #[prost(string, tag = "3")]
+        pub id: String,
+        #[prost(map = "string, message", tag = "4")]
+        pub parameters: HashMap<String, InferParameter>,
+        #[prost(message, repeated, tag = "5")]
+        pub inputs: Vec<InferInputTensor>,
+        #[prost(message, repeated, tag = "6")]
+        pub outputs: Vec<InferRequestedOutputTensor>,
+        // One entry per input, in order, replacing its `contents`
+        #[prost(bytes = "vec", repeated, tag = "7")]
+        pub raw_input_contents: Vec<Vec<u8>>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InferInputTensor {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub datatype: String,
+        #[prost(int64, repeated, tag = "3")]
+        pub shape: Vec<i64>,
+        #[prost(map = "string, message", tag = "4")]
+        pub parameters: HashMap<String, InferParameter>,
+        #[prost(message, optional, tag = "5")]
+        pub contents: Option<InferTensorContents>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InferRequestedOutputTensor {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(map = "string, message", tag = "2")]
+        pub parameters: HashMap<String, InferParameter>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ModelInferResponse {
+        #[prost(string, tag = "1")]
+        pub model_name: String,
+        #[prost(string, tag = "2")]
+        pub model_version: String,
+        #[prost(string, tag = "3")]
+        pub id: String,
+        #[prost(map = "string, message", tag = "4")]
+        pub parameters: HashMap<String, InferParameter>,
+        #[prost(message, repeated, tag = "5")]
+        pub outputs: Vec<InferOutputTensor>,
+        #[prost(bytes = "vec", repeated, tag = "6")]
+        pub raw_output_contents: Vec<Vec<u8>>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InferOutputTensor {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub datatype: String,
+        #[prost(int64, repeated, tag = "3")]
+        pub shape: Vec<i64>,
+        #[prost(map = "string, message", tag = "4")]
+        pub parameters: HashMap<String, InferParameter>,
+        #[prost(message, optional, tag = "5")]
+        pub contents: Option<InferTensorContents>,
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InferParameter {
+        #[prost(oneof = "ParameterChoice", tags = "1, 2, 3, 4, 5")]
+        pub parameter_choice: Option<ParameterChoice>,
+    }
+    
+    // Variants named as the proto's oneof fields are
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum ParameterChoice {
+        #[prost(bool, tag = "1")]
+        BoolParam(bool),
+        #[prost(int64, tag = "2")]
+        Int64Param(i64),
+        #[prost(string, tag = "3")]
+        StringParam(String),
+        #[prost(double, tag = "4")]
+        DoubleParam(f64),
+        #[prost(uint64, tag = "5")]
+        Uint64Param(u64),
+    }
+    
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InferTensorContents {
+        #[prost(bool, repeated, tag = "1")]
+        pub bool_contents: Vec<bool>,
+        #[prost(int32, repeated, tag = "2")]
+        pub int_contents: Vec<i32>,
+        #[prost(int64, repeated, tag = "3")]
+        pub int64_contents: Vec<i64>,
+        #[prost(uint32, repeated, tag = "4")]
+        pub uint_contents: Vec<u32>,
+        #[prost(uint64, repeated, tag = "5")]
+        pub uint64_contents: Vec<u64>,
+        #[prost(float, repeated, tag = "6")]
+        pub fp32_contents: Vec<f32>,
+        #[prost(double, repeated, tag = "7")]
+        pub fp64_contents: Vec<f64>,
+        #[prost(bytes = "vec", repeated, tag = "8")]
+        pub bytes_contents: Vec<Vec<u8>>,
+    }
+}
+
+// Protocol datatypes and the names the serving API uses for them
+const DATATYPES: &[(&str, &str)] = &[
+    ("BOOL", "bool"),
+    ("UINT8", "uint8"),
+    ("UINT16", "uint16"),
+    ("UINT32", "uint32"),
+    ("UINT64", "uint64"),
+    ("INT8", "int8"),
+    ("INT16", "int16"),
+    ("INT32", "int32"),
+    ("INT64", "int64"),
+    ("FP16", "float16"),
+    ("FP32", "float32"),
+    ("FP64", "float64"),
+    ("BYTES", "bytes"),
+    ("BYTES", "string"),
+];
+
+// Start serving on `addr` from a thread of its own. Binding happens here so
+// that a taken port is reported before the server starts.
+pub fn spawn(
+    addr: &str,
+    models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+    traffic: Arc<Mutex<ABTestManager>>,
+    metrics: Arc<Mutex<ServerMetrics>>,
+    access: Arc<AccessControl>,
+    config: &ServerConfig,
+) -> Result<(), ServerError> {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .map_err(|e| ServerError::BindError(e.to_string()))?;
+    
+    // Predictions block, so they run on the runtime's blocking pool, sized
+    // like the HTTP worker pool
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .max_blocking_threads(config.num_workers.max(1))
+        .enable_all()
+        .build()
+        .map_err(|e| ServerError::IoError(e.to_string()))?;
+    let service = InferenceServer {
+        models,
+        traffic,
+        metrics,
+        access,
+        caller: None,
+        max_message_size: config.max_body_size,
+        access_log: config.access_log,
+    };
+    
+    thread::spawn(move || {
+        let result = runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .map_err(std::io::Error::other)
+        });
+        if let Err(e) = result {
+            eprintln!("gRPC endpoint stopped: {}", e);
+        }
+    });
+    
+    Ok(())
+}
+
+// The GRPCInferenceService, over the same registry as the HTTP front end
+#[derive(Clone)]
+struct InferenceServer {
+    models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+    traffic: Arc<Mutex<ABTestManager>>,
+    metrics: Arc<Mutex<ServerMetrics>>,
+    access: Arc<AccessControl>,
+    // Who is calling, set on the copy handling each call
+    caller: Option<Caller>,
+    max_message_size: usize,
+    access_log: bool,
+}
+
+impl InferenceServer {
+    fn server_ready(&self) -> ServerReadyResponse {
+        ServerReadyResponse {
+            ready: !self.models.read().unwrap().is_empty(),
+        }
+    }
+    
+    fn server_metadata(&self) -> ServerMetadataResponse {
+        ServerMetadataResponse {
+            name: "rustos-model-server".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            extensions: Vec::new(),
+        }
+    }
+    
+    // Key of the version a request names, or of the model's stable version
+    fn key(&self, name: &str, requested: &str) -> String {
+        match version(requested) {
+            Some(requested) => model_key(name, Some(requested)),
+            None => model_key(name, self.traffic.lock().unwrap().rollout(name).map(|rollout| rollout.stable).as_deref()),
+        }
+    }
+    
+    fn authorize(&self, model: &str) -> Result<(), Status> {
+        match &self.caller {
+            Some(caller) if self.access.may_use(caller, model) => Ok(()),
+            _ => Err(Status::permission_denied(format!("Not allowed to use model {}", model))),
+        }
+    }
+    
+    fn model_ready(&self, request: ModelReadyRequest) -> Result<ModelReadyResponse, Status> {
+        self.authorize(&request.name)?;
+        let key = self.key(&request.name, &request.version);
+        Ok(ModelReadyResponse {
+            ready: self.models.read().unwrap().contains_key(&key),
+        })
+    }
+    
+    fn model_metadata(&self, request: ModelMetadataRequest) -> Result<ModelMetadataResponse, Status> {
+        self.authorize(&request.name)?;
+        let key = self.key(&request.name, &request.version);
+        let models = self.models.read().unwrap();
+        let endpoint = models.get(&key).ok_or_else(|| {
+            let known = models.values().any(|endpoint| endpoint.name == request.name);
+            status(if known { ModelError::VersionNotFound } else { ModelError::ModelNotFound })
+        })?;
+        let info = endpoint.model.read().unwrap().get_info();
+        
+        let mut versions: Vec<String> = models.values()
+            .filter(|endpoint| endpoint.name == request.name)
+            .map(|endpoint| endpoint.version.clone())
+            .collect();
+        versions.sort_by(|a, b| compare_versions(a, b));
+        
+        let metadata = |spec: &super::TensorSpec| TensorMetadata {
+            name: spec.name.clone(),
+            datatype: datatype(&spec.dtype),
+            shape: spec.shape.iter().map(|&dimension| dimension as i64).collect(),
+        };
+        Ok(ModelMetadataResponse {
+            name: request.name,
+            versions,
+            platform: info.framework,
+            inputs: info.inputs.iter().map(metadata).collect(),
+            outputs: info.outputs.iter().map(metadata).collect(),
+        })
+    }
+    
+    fn model_infer(&self, request: ModelInferRequest) -> Result<ModelInferResponse, Status> {
+        self.authorize(&request.model_name)?;
+        let raw = !request.raw_input_contents.is_empty();
+        if raw && request.raw_input_contents.len() != request.inputs.len() {
+            return Err(Status::invalid_argument("raw_input_contents must have one entry per input"));
+        }
+        
+        let mut inputs = HashMap::new();
+        for (index, input) in request.inputs.iter().enumerate() {
+            let contents = request.raw_input_contents.get(index).map(Vec::as_slice);
+            inputs.insert(input.name.clone(), input_tensor(input, contents)?);
+        }
+        let parameters: HashMap<String, String> = request.parameters.iter()
+            .map(|(name, parameter)| (name.clone(), parameter_string(parameter)))
+            .collect();
+        
+        let predict_request = PredictRequest {
+            id: request.id.clone(),
+            model_name: request.model_name.clone(),
+            model_version: version(&request.model_version).map(str::to_string),
+            inputs,
+            parameters: (!parameters.is_empty()).then_some(parameters),
+        };
+        let mut response = predict(&predict_request, &self.models, &self.traffic, &self.metrics).map_err(status)?;
+        
+        // Requested outputs in the order asked for, otherwise all by name
+        let names: Vec<String> = if request.outputs.is_empty() {
+            let mut names: Vec<String> = response.outputs.keys().cloned().collect();
+            names.sort();
+            names
+        } else {
+            request.outputs.iter().map(|output| output.name.clone()).collect()
+        };
+        
+        let mut outputs = Vec::new();
+        let mut raw_output_contents = Vec::new();
+        for name in names {
+            let tensor = response.outputs.remove(&name)
+                .ok_or_else(|| Status::invalid_argument(format!("the model has no output '{}'", name)))?;
+            let (output, contents) = output_tensor(name, tensor, raw);
+            outputs.push(output);
+            raw_output_contents.extend(contents);
+        }
+        
+        Ok(ModelInferResponse {
+            model_name: response.model_name,
+            model_version: response.model_version,
+            id: response.id,
+            parameters: HashMap::new(),
+            outputs,
+            raw_output_contents,
+        })
+    }
+}
+
+// An empty version means the latest
+fn version(version: &str) -> Option<&str> {
+    (!version.is_empty()).then_some(version)
+}
+
+fn status(error: ModelError) -> Status {
+    let code = match error {
+        ModelError::InvalidInput(_) => Code::InvalidArgument,
+        ModelError::InferenceError(_) => Code::Internal,
+        ModelError::TimeoutError => Code::DeadlineExceeded,
+        ModelError::ModelNotFound | ModelError::VersionNotFound => Code::NotFound,
+    };
+    Status::new(code, error.to_string())
+}
+
+// Protocol datatype for a serving dtype name
+fn datatype(dtype: &str) -> String {
+    DATATYPES.iter()
+        .find(|(_, name)| name.eq_ignore_ascii_case(dtype))
+        .map(|(datatype, _)| datatype.to_string())
+        .unwrap_or_else(|| dtype.to_uppercase())
+}
+
+fn parameter_string(parameter: &InferParameter) -> String {
+    match &parameter.parameter_choice {
+        Some(ParameterChoice::BoolParam(value)) => value.to_string(),
+        Some(ParameterChoice::Int64Param(value)) => value.to_string(),
+        Some(ParameterChoice::StringParam(value)) => value.clone(),
+        Some(ParameterChoice::DoubleParam(value)) => value.to_string(),
+        Some(ParameterChoice::Uint64Param(value)) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+// Decode an input from its typed contents or, when given, its raw
+// little-endian bytes. Only the datatypes `TensorValues` can hold are taken.
+fn input_tensor(input: &InferInputTensor, raw: Option<&[u8]>) -> Result<TensorData, Status> {
+    let invalid = |message: String| Status::invalid_argument(format!("input '{}': {}", input.name, message));
+    
+    let shape = input.shape.iter()
+        .map(|&dimension| usize::try_from(dimension).map_err(|_| invalid(format!("bad dimension {}", dimension))))
+        .collect::<Result<Vec<usize>, Status>>()?;
+    let contents = input.contents.clone().unwrap_or_default();
+    
+    let (dtype, data) = match (input.datatype.as_str(), raw) {
+        ("FP32", Some(raw)) => ("float32", TensorValues::Float32(from_le(raw, f32::from_le_bytes).map_err(invalid)?)),
+        ("FP32", None) => ("float32", TensorValues::Float32(contents.fp32_contents)),
+        ("FP64", Some(raw)) => ("float64", TensorValues::Float64(from_le(raw, f64::from_le_bytes).map_err(invalid)?)),
+        ("FP64", None) => ("float64", TensorValues::Float64(contents.fp64_contents)),
+        ("INT32", Some(raw)) => ("int32", TensorValues::Int32(from_le(raw, i32::from_le_bytes).map_err(invalid)?)),
+        ("INT32", None) => ("int32", TensorValues::Int32(contents.int_contents)),
+        ("INT64", Some(raw)) => ("int64", TensorValues::Int64(from_le(raw, i64::from_le_bytes).map_err(invalid)?)),
+        ("INT64", None) => ("int64", TensorValues::Int64(contents.int64_contents)),
+        ("BYTES", Some(raw)) => ("bytes", TensorValues::Bytes(split_bytes(raw).map_err(invalid)?)),
+        ("BYTES", None) => ("bytes", TensorValues::Bytes(contents.bytes_contents)),
+        (datatype, _) => return Err(invalid(format!("unsupported datatype {}", datatype))),
+    };
+    
+    let expected = shape.iter().try_fold(1usize, |count, &dimension| count.checked_mul(dimension));
+    let count = match &data {
+        TensorValues::Float32(values) => values.len(),
+        TensorValues::Float64(values) => values.len(),
+        TensorValues::Int32(values) => values.len(),
+        TensorValues::Int64(values) => values.len(),
+        TensorValues::String(values) => values.len(),
+        TensorValues::Bytes(values) => values.len(),
+    };
+    if expected != Some(count) {
+        return Err(invalid(format!("{} elements do not fill shape {:?}", count, shape)));
+    }
+    
+    Ok(TensorData {
+        shape,
+        dtype: dtype.to_string(),
+        data,
+    })
+}
+
+// Encode an output as typed contents, or as raw bytes when the request sent
+// its inputs that way
+fn output_tensor(name: String, tensor: TensorData, raw: bool) -> (InferOutputTensor, Option<Vec<u8>>) {
+    let mut contents = InferTensorContents::default();
+    let mut bytes = Vec::new();
+    let datatype = match tensor.data {
+        TensorValues::Float32(values) => {
+            values.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+            contents.fp32_contents = values;
+            "FP32"
+        },
+        TensorValues::Float64(values) => {
+            values.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+            contents.fp64_contents = values;
+            "FP64"
+        },
+        TensorValues::Int32(values) => {
+            values.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+            contents.int_contents = values;
+            "INT32"
+        },
+        TensorValues::Int64(values) => {
+            values.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+            contents.int64_contents = values;
+            "INT64"
+        },
+        TensorValues::String(values) => {
+            contents.bytes_contents = values.into_iter().map(String::into_bytes).collect();
+            bytes = join_bytes(&contents.bytes_contents);
+            "BYTES"
+        },
+        TensorValues::Bytes(values) => {
+            bytes = join_bytes(&values);
+            contents.bytes_contents = values;
+            "BYTES"
+        },
+    };
+    
+    let output = InferOutputTensor {
+        name,
+        datatype: datatype.to_string(),
+        shape: tensor.shape.iter().map(|&dimension| dimension as i64).collect(),
+        parameters: HashMap::new(),
+        contents: (!raw).then_some(contents),
+    };
+    (output, raw.then_some(bytes))
+}
+
+fn from_le<const N: usize, T>(raw: &[u8], convert: fn([u8; N]) -> T) -> Result<Vec<T>, String> {
+    if !raw.len().is_multiple_of(N) {
+        return Err(format!("{} raw bytes are not a whole number of {}-byte elements", raw.len(), N));
+    }
+    Ok(raw.chunks_exact(N).map(|chunk| convert(chunk.try_into().unwrap())).collect())
+}
+
+// Raw BYTES elements are each prefixed with their length as a little-endian u32
+fn split_bytes(mut raw: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut elements = Vec::new();
+    while !raw.is_empty() {
+        let Some((length, rest)) = raw.split_first_chunk::<4>() else {
+            return Err("truncated BYTES element length".to_string());
+        };
+        let length = u32::from_le_bytes(*length) as usize;
+        if rest.len() < length {
+            return Err("truncated BYTES element".to_string());
+        }
+        elements.push(rest[..length].to_vec());
+        raw = &rest[length..];
+    }
+    Ok(elements)
+}
+
+fn join_bytes(elements: &[Vec<u8>]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for element in elements {
+        raw.extend_from_slice(&(element.len() as u32).to_le_bytes());
+        raw.extend_from_slice(element);
+    }
+    raw
+}
+
+// A unary RPC. Handlers may run a model, which blocks, so they run on the
+// blocking pool.
+struct Unary<F> {
+    server: InferenceServer,
+    handler: F,
+}
+
+impl<F, Req, Res> UnaryService<Req> for Unary<F>
+where
+    Req: Send + 'static,
+    Res: Send + 'static,
+    F: FnOnce(InferenceServer, Req) -> Result<Res, Status> + Clone + Send + 'static,
+{
+    type Response = Res;
+    type Future = BoxFuture<Response<Res>, Status>;
+    
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        let server = self.server.clone();
+        let handler = self.handler.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || handler(server, request.into_inner()))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map(Response::new)
+        })
+    }
+}
+
+impl InferenceServer {
+    // Decode, handle and encode one unary call
+    fn unary<B, Req, Res, F>(&self, request: http::Request<B>, handler: F) -> BoxFuture<http::Response<tonic::body::BoxBody>, Infallible>
+    where
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+        Req: prost::Message + Default + Send + 'static,
+        Res: prost::Message + Send + 'static,
+        F: FnOnce(InferenceServer, Req) -> Result<Res, Status> + Clone + Send + 'static,
+    {
+        let service = Unary {
+            server: self.clone(),
+            handler,
+        };
+        let max_message_size = self.max_message_size;
+        Box::pin(async move {
+            let mut grpc = Grpc::new(ProstCodec::<Res, Req>::default())
+                .max_decoding_message_size(max_message_size)
+                .max_encoding_message_size(usize::MAX);
+            Ok(grpc.unary(service, request).await)
+        })
+    }
+}
+
+impl<B> Service<http::Request<B>> for InferenceServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+    
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let started = Instant::now();
+        let path = request.uri().path().to_string();
+        let request_id = logging::request_id(request.headers().get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()));
+        let peer = request.extensions().get::<TcpConnectInfo>().and_then(TcpConnectInfo::remote_addr);
+        
+        // Liveness and readiness are open to all, like /health over HTTP
+        let public = matches!(
+            path.as_str(),
+            "/inference.GRPCInferenceService/ServerLive" | "/inference.GRPCInferenceService/ServerReady"
+        );
+        let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok());
+        let admitted = match public {
+            true => Ok(Caller { client: None, admin: false }),
+            false => self.access.admit(
+                header(auth::API_KEY_HEADER),
+                header(auth::AUTHORIZATION_HEADER),
+                peer.map(|peer| peer.ip()),
+                started,
+            ),
+        };
+        let server = match admitted {
+            Ok(caller) => InferenceServer { caller: Some(caller), ..self.clone() },
+            Err(denied) => {
+                let response = denied_status(&denied).into_http();
+                return self.finish(Box::pin(async move { Ok(response) }), started, path, request_id, peer, None);
+            }
+        };
+        let client = server.caller.as_ref().and_then(|caller| caller.client.clone());
+        
+        let response = match path.as_str() {
+            "/inference.GRPCInferenceService/ServerLive" => {
+                server.unary(request, |_, _: ServerLiveRequest| Ok(ServerLiveResponse { live: true }))
+            },
+            "/inference.GRPCInferenceService/ServerReady" => {
+                server.unary(request, |server, _: ServerReadyRequest| Ok(server.server_ready()))
+            },
+            "/inference.GRPCInferenceService/ModelReady" => {
+                server.unary(request, |server, message| server.model_ready(message))
+            },
+            "/inference.GRPCInferenceService/ServerMetadata" => {
+                server.unary(request, |server, _: ServerMetadataRequest| Ok(server.server_metadata()))
+            },
+            "/inference.GRPCInferenceService/ModelMetadata" => {
+                server.unary(request, |server, message| server.model_metadata(message))
+            },
+            "/inference.GRPCInferenceService/ModelInfer" => {
+                server.unary(request, |server, message| server.model_infer(message))
+            },
+            _ => Box::pin(async move {
+                let response = http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", Code::Unimplemented as i32)
+                    .header(http::header::CONTENT_TYPE, "application/grpc")
+                    .body(empty_body())
+                    .unwrap();
+                Ok(response)
+            }),
+        };
+        self.finish(response, started, path, request_id, peer, client)
+    }
+}
+
+impl InferenceServer {
+    // Count and log a call once its response is ready, and tag it with the
+    // request ID. Failures come back as trailers-only responses, with the
+    // status in the headers.
+    fn finish(
+        &self,
+        response: BoxFuture<http::Response<tonic::body::BoxBody>, Infallible>,
+        started: Instant,
+        path: String,
+        request_id: String,
+        peer: Option<SocketAddr>,
+        client: Option<String>,
+    ) -> BoxFuture<http::Response<tonic::body::BoxBody>, Infallible> {
+        let metrics = Arc::clone(&self.metrics);
+        let access_log = self.access_log;
+        Box::pin(async move {
+            let mut response = response.await?;
+            let status = Status::from_header_map(response.headers());
+            let code = status.as_ref().map_or(Code::Ok, Status::code);
+            let elapsed = started.elapsed();
+            
+            metrics.lock().unwrap().observe_response("grpc", &format!("{:?}", code), elapsed);
+            if access_log {
+                let mut log = AccessLog::new(&request_id, "grpc", "POST", &path, code as u16, elapsed);
+                log.peer = peer.map(|peer| peer.to_string());
+                log.client = client.as_deref();
+                log.error = status.as_ref().map(Status::message);
+                log.write();
+            }
+            if let Ok(value) = http::HeaderValue::from_str(&request_id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+// The status for a call turned away. A rate-limited client is told when to
+// retry in `retry-after` metadata, as over HTTP.
+fn denied_status(denied: &Denied) -> Status {
+    let code = match denied {
+        Denied::MissingCredentials | Denied::InvalidCredentials => Code::Unauthenticated,
+        Denied::RateLimited(_) => Code::ResourceExhausted,
+    };
+    let mut metadata = MetadataMap::new();
+    if let Some(seconds) = denied.retry_after() {
+        metadata.insert("retry-after", seconds.into());
+    }
+    Status::with_metadata(code, denied.to_string(), metadata)
+}
+
+impl NamedService for InferenceServer {
+    const NAME: &'static str = "inference.GRPCInferenceService";
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Model, ModelInfo, PredictResponse, TensorSpec};
+    
+    // Doubles its float input `x` into output `y`
+    struct Doubler;
+    
+    impl Model for Doubler {
+        fn predict(&self, input: &PredictRequest) -> Result<PredictResponse, ModelError> {
+            let x = input.inputs.get("x").ok_or_else(|| ModelError::InvalidInput("missing input 'x'".to_string()))?;
+            let TensorValues::Float32(values) = &x.data else {
+                return Err(ModelError::InvalidInput("x must be float32".to_string()));
+            };
+            let y = TensorData {
+                shape: x.shape.clone(),
+                dtype: "float32".to_string(),
+                data: TensorValues::Float32(values.iter().map(|value| value * 2.0).collect()),
+            };
+            Ok(PredictResponse {
+                id: input.id.clone(),
+                model_name: input.model_name.clone(),
+                model_version: "1".to_string(),
+                outputs: HashMap::from([("y".to_string(), y)]),
+                metadata: None,
+            })
+        }
+        
+        fn batch_predict(&self, inputs: &[PredictRequest]) -> Result<Vec<PredictResponse>, ModelError> {
+            inputs.iter().map(|input| self.predict(input)).collect()
+        }
+        
+        fn get_info(&self) -> ModelInfo {
+            let spec = |name: &str| TensorSpec { name: name.to_string(), shape: vec![-1], dtype: "float32".to_string() };
+            ModelInfo {
+                name: "double".to_string(),
+                version: "1".to_string(),
+                framework: "test".to_string(),
+                inputs: vec![spec("x")],
+                outputs: vec![spec("y")],
+                metadata: HashMap::new(),
+            }
+        }
+    }
+    
+    // Serve the doubler on a free local port and make `calls` against it
+    // through a real gRPC channel
+    fn with_server<F, Fut>(calls: F) -> Arc<Mutex<ServerMetrics>>
+    where
+        F: FnOnce(tonic::client::Grpc<tonic::transport::Channel>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        let endpoint = ModelEndpoint::new("double".to_string(), "1".to_string(), Box::new(Doubler));
+        let models = HashMap::from([(model_key("double", None), endpoint)]);
+        let metrics = Arc::new(Mutex::new(ServerMetrics::new()));
+        let service = InferenceServer {
+            models: Arc::new(RwLock::new(models)),
+            traffic: Arc::new(Mutex::new(ABTestManager::new())),
+            metrics: Arc::clone(&metrics),
+            access: Arc::new(AccessControl::new(None, None)),
+            caller: None,
+            max_message_size: 1 << 20,
+            access_log: false,
+        };
+        
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(Server::builder().add_service(service).serve_with_incoming(TcpListenerStream::new(listener)));
+            
+            let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr))
+                .unwrap()
+                .connect()
+                .await
+                .unwrap();
+            calls(tonic::client::Grpc::new(channel)).await;
+        });
+        metrics
+    }
+    
+    async fn infer(
+        client: &mut tonic::client::Grpc<tonic::transport::Channel>,
+        request: ModelInferRequest,
+    ) -> Result<ModelInferResponse, Status> {
+        client.ready().await.unwrap();
+        let path = http::uri::PathAndQuery::from_static("/inference.GRPCInferenceService/ModelInfer");
+        let codec = ProstCodec::<ModelInferRequest, ModelInferResponse>::default();
+        client.unary(Request::new(request), path, codec).await.map(Response::into_inner)
+    }
+    
+    fn input(shape: &[i64], contents: Option<InferTensorContents>) -> InferInputTensor {
+        InferInputTensor {
+            name: "x".to_string(),
+            datatype: "FP32".to_string(),
+            shape: shape.to_vec(),
+            parameters: HashMap::new(),
+            contents,
+        }
+    }
+    
+    fn request(inputs: Vec<InferInputTensor>, raw_input_contents: Vec<Vec<u8>>) -> ModelInferRequest {
+        ModelInferRequest {
+            model_name: "double".to_string(),
+            model_version: String::new(),
+            id: "req-1".to_string(),
+            parameters: HashMap::new(),
+            inputs,
+            outputs: Vec::new(),
+            raw_input_contents,
+        }
+    }
+    
+    #[test]
+    fn predicts_with_typed_contents() {
+        let metrics = with_server(|mut client| async move {
+            let contents = InferTensorContents { fp32_contents: vec![1.0, 2.5, -3.0], ..Default::default() };
+            let response = infer(&mut client, request(vec![input(&[1, 3], Some(contents))], Vec::new())).await.unwrap();
+            assert_eq!((response.model_name.as_str(), response.model_version.as_str()), ("double", "1"));
+            assert_eq!(response.id, "req-1");
+            assert!(response.raw_output_contents.is_empty());
+            let [output] = response.outputs.as_slice() else { panic!("{:?}", response.outputs) };
+            assert_eq!((output.name.as_str(), output.datatype.as_str()), ("y", "FP32"));
+            assert_eq!(output.shape, [1, 3]);
+            assert_eq!(output.contents.as_ref().unwrap().fp32_contents, [2.0, 5.0, -6.0]);
+        });
+        // Counted like any other front end's calls
+        let rendered = metrics.lock().unwrap().render();
+        assert!(rendered.contains("model_server_responses_total{protocol=\"grpc\",code=\"Ok\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("model_predictions_total{model=\"double\",version=\"1\",outcome=\"success\"} 1\n"), "{}", rendered);
+    }
+    
+    #[test]
+    fn answers_raw_inputs_with_raw_outputs() {
+        with_server(|mut client| async move {
+            let raw: Vec<u8> = [0.5f32, 4.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+            let response = infer(&mut client, request(vec![input(&[2], None)], vec![raw])).await.unwrap();
+            assert_eq!(response.outputs.len(), 1);
+            assert!(response.outputs[0].contents.is_none());
+            let expected: Vec<u8> = [1.0f32, 8.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+            assert_eq!(response.raw_output_contents, [expected]);
+        });
+    }
+    
+    #[test]
+    fn reports_errors_as_status_codes() {
+        with_server(|mut client| async move {
+            let mut unknown = request(vec![input(&[0], None)], Vec::new());
+            unknown.model_name = "missing".to_string();
+            assert_eq!(infer(&mut client, unknown).await.unwrap_err().code(), Code::NotFound);
+            
+            let mut old = request(vec![input(&[0], None)], Vec::new());
+            old.model_version = "7".to_string();
+            assert_eq!(infer(&mut client, old).await.unwrap_err().code(), Code::NotFound);
+            
+            let short = InferTensorContents { fp32_contents: vec![1.0], ..Default::default() };
+            let status = infer(&mut client, request(vec![input(&[2], Some(short))], Vec::new())).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            assert!(status.message().contains("do not fill shape"), "{}", status.message());
+            
+            // One raw entry for two inputs
+            let two = vec![input(&[1], None), input(&[1], None)];
+            let status = infer(&mut client, request(two, vec![vec![0; 4]])).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+            
+            let mut asked = request(vec![input(&[0], None)], Vec::new());
+            asked.outputs.push(InferRequestedOutputTensor { name: "z".to_string(), parameters: HashMap::new() });
+            assert_eq!(infer(&mut client, asked).await.unwrap_err().code(), Code::InvalidArgument);
+        });
+    }
+}
@@ -0,0 +1,653 @@
+// HTTP/1.1 message parsing and encoding for the model server
+use std::fmt;
+
+use serde::Serialize;
+
+// Largest request line plus header section accepted
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+pub const MAX_HEADERS: usize = 100;
+
+// Largest chunk-size line accepted, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+
+// Protocol version of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+// A complete request with its body decoded
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: Version,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    // First value of a header, looked up case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    
+    // Request target without the query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+    
+    // Whether the connection stays open once this request is answered
+    pub fn keep_alive(&self) -> bool {
+        let mut close = false;
+        let mut keep = false;
+        for token in header_tokens(&self.headers, "connection") {
+            close |= token.eq_ignore_ascii_case("close");
+            keep |= token.eq_ignore_ascii_case("keep-alive");
+        }
+        
+        match self.version {
+            Version::Http11 => !close,
+            Version::Http10 => keep && !close,
+        }
+    }
+}
+
+// Why a request could not be parsed. The connection cannot be trusted to be
+// in step after any of these, so it is closed once the error is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    BadRequest(&'static str),
+    HeadTooLarge,
+    PayloadTooLarge,
+    UnsupportedEncoding,
+    UnsupportedVersion,
+}
+
+impl ParseError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::BadRequest(_) => 400,
+            ParseError::PayloadTooLarge => 413,
+            ParseError::HeadTooLarge => 431,
+            ParseError::UnsupportedEncoding => 501,
+            ParseError::UnsupportedVersion => 505,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ParseError::HeadTooLarge => write!(f, "request head exceeds {} bytes or {} headers", MAX_HEAD_SIZE, MAX_HEADERS),
+            ParseError::PayloadTooLarge => write!(f, "request body is too large"),
+            ParseError::UnsupportedEncoding => write!(f, "only the chunked transfer coding is supported"),
+            ParseError::UnsupportedVersion => write!(f, "only HTTP/1.x is supported"),
+        }
+    }
+}
+
+// How the body of the request being read is delimited
+enum Framing {
+    // Bytes still to come
+    Length(usize),
+    Chunked(Chunk),
+}
+
+// Position within a chunked body
+enum Chunk {
+    Size,
+    // Bytes of the current chunk still to come
+    Data(usize),
+    // The CRLF after a chunk's data
+    DataEnd,
+    // Trailer fields are read past and dropped; counts the bytes seen
+    Trailers(usize),
+}
+
+enum State {
+    // Bytes already searched for the end of the head
+    Head { scanned: usize },
+    Body { request: Request, framing: Framing },
+}
+
+// Incremental request parser. Bytes are handed over as they arrive; each call
+// consumes what it can and yields at most one request, so pipelined requests
+// come out one at a time and in order. Body bytes are moved into the request
+// as soon as they are seen, so the caller never buffers more than a head.
+pub struct Parser {
+    state: State,
+    max_body: usize,
+    expect_continue: bool,
+}
+
+impl Parser {
+    pub fn new(max_body: usize) -> Self {
+        Self {
+            state: State::Head { scanned: 0 },
+            max_body,
+            expect_continue: false,
+        }
+    }
+    
+    // Whether part of a request has been taken in
+    pub fn in_progress(&self) -> bool {
+        matches!(self.state, State::Body { .. })
+    }
+    
+    // Whether the client waits for "100 Continue" before sending the body it
+    // announced. Reported once per request.
+    pub fn take_continue(&mut self) -> bool {
+        std::mem::take(&mut self.expect_continue)
+    }
+    
+    // Parse from the start of `input`, returning how many bytes were consumed
+    // and the request, if one was completed. Consumed bytes must be dropped
+    // from the front of the buffer before the next call.
+    pub fn parse(&mut self, input: &[u8]) -> Result<(usize, Option<Request>), ParseError> {
+        let mut consumed = 0;
+        
+        if let State::Head { scanned } = &mut self.state {
+            // Empty lines between pipelined requests are allowed
+            while input[consumed..].starts_with(b"\r\n") {
+                consumed += 2;
+            }
+            let rest = &input[consumed..];
+            
+            let Some(end) = find(rest, scanned.saturating_sub(3), b"\r\n\r\n") else {
+                if rest.len() > MAX_HEAD_SIZE {
+                    return Err(ParseError::HeadTooLarge);
+                }
+                *scanned = rest.len();
+                return Ok((consumed, None));
+            };
+            if end > MAX_HEAD_SIZE {
+                return Err(ParseError::HeadTooLarge);
+            }
+            
+            let request = parse_head(&rest[..end])?;
+            let framing = self.framing(&request)?;
+            consumed += end + 4;
+            
+            let has_body = !matches!(framing, Framing::Length(0));
+            self.expect_continue = has_body
+                && request.version == Version::Http11
+                && request.header("expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue"));
+            self.state = State::Body { request, framing };
+        }
+        
+        let State::Body { request, framing } = &mut self.state else {
+            unreachable!();
+        };
+        let (used, done) = read_body(request, framing, &input[consumed..], self.max_body)?;
+        consumed += used;
+        if !done {
+            return Ok((consumed, None));
+        }
+        
+        match std::mem::replace(&mut self.state, State::Head { scanned: 0 }) {
+            State::Body { request, .. } => Ok((consumed, Some(request))),
+            State::Head { .. } => unreachable!(),
+        }
+    }
+    
+    // Work out how the body is delimited, refusing the ambiguous cases that
+    // let requests be smuggled past a proxy
+    fn framing(&self, request: &Request) -> Result<Framing, ParseError> {
+        let encodings: Vec<&str> = header_tokens(&request.headers, "transfer-encoding").collect();
+        let lengths: Vec<&str> = header_tokens(&request.headers, "content-length").collect();
+        
+        if request.header("transfer-encoding").is_some() {
+            if request.version == Version::Http10 {
+                return Err(ParseError::BadRequest("transfer-encoding in an HTTP/1.0 request"));
+            }
+            if request.header("content-length").is_some() {
+                return Err(ParseError::BadRequest("both content-length and transfer-encoding given"));
+            }
+            return match encodings.as_slice() {
+                [encoding] if encoding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked(Chunk::Size)),
+                [.., last] if last.eq_ignore_ascii_case("chunked") => Err(ParseError::UnsupportedEncoding),
+                _ => Err(ParseError::BadRequest("request body is not chunked")),
+            };
+        }
+        
+        let mut length = None;
+        for value in lengths {
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(ParseError::BadRequest("invalid content-length"));
+            }
+            let value = value.parse::<usize>().map_err(|_| ParseError::PayloadTooLarge)?;
+            if length.is_some_and(|length| length != value) {
+                return Err(ParseError::BadRequest("conflicting content-length values"));
+            }
+            length = Some(value);
+        }
+        
+        let length = length.unwrap_or(0);
+        if length > self.max_body {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        Ok(Framing::Length(length))
+    }
+}
+
+// Request line and header fields, without the blank line that ends them
+fn parse_head(head: &[u8]) -> Result<Request, ParseError> {
+    let head = std::str::from_utf8(head).map_err(|_| ParseError::BadRequest("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    
+    let mut parts = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(ParseError::BadRequest("malformed request line"));
+    };
+    if method.is_empty() || !method.bytes().all(is_token) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if target.is_empty() || target.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    let version = match version.strip_prefix("HTTP/") {
+        Some("1.0") => Version::Http10,
+        // Later 1.x minor versions are answered as 1.1
+        Some(number) if number.len() == 3 && number.starts_with("1.") && number.as_bytes()[2].is_ascii_digit() => {
+            Version::Http11
+        }
+        Some(number) if number.bytes().all(|byte| byte.is_ascii_digit() || byte == b'.') => {
+            return Err(ParseError::UnsupportedVersion);
+        }
+        _ => return Err(ParseError::BadRequest("malformed HTTP version")),
+    };
+    
+    let mut headers = Vec::new();
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            return Err(ParseError::BadRequest("obsolete header line folding"));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::BadRequest("malformed header line"));
+        };
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(ParseError::BadRequest("invalid header name"));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(ParseError::HeadTooLarge);
+        }
+        headers.push((name.to_string(), value.trim_matches([' ', '\t']).to_string()));
+    }
+    
+    let request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version,
+        headers,
+        body: Vec::new(),
+    };
+    if request.version == Version::Http11 && request.header("host").is_none() {
+        return Err(ParseError::BadRequest("missing Host header"));
+    }
+    Ok(request)
+}
+
+// Move body bytes from `input` into the request. Returns how many bytes were
+// used and whether the body is complete.
+fn read_body(
+    request: &mut Request,
+    framing: &mut Framing,
+    input: &[u8],
+    max_body: usize,
+) -> Result<(usize, bool), ParseError> {
+    let chunk = match framing {
+        Framing::Length(remaining) => {
+            let take = (*remaining).min(input.len());
+            request.body.extend_from_slice(&input[..take]);
+            *remaining -= take;
+            return Ok((take, *remaining == 0));
+        }
+        Framing::Chunked(chunk) => chunk,
+    };
+    
+    let mut consumed = 0;
+    loop {
+        let rest = &input[consumed..];
+        match chunk {
+            Chunk::Size => {
+                let Some(end) = find(rest, 0, b"\r\n") else {
+                    if rest.len() > MAX_CHUNK_LINE {
+                        return Err(ParseError::BadRequest("chunk size line too long"));
+                    }
+                    return Ok((consumed, false));
+                };
+                let size = parse_chunk_size(&rest[..end])?;
+                if request.body.len().checked_add(size).is_none_or(|total| total > max_body) {
+                    return Err(ParseError::PayloadTooLarge);
+                }
+                consumed += end + 2;
+                *chunk = if size == 0 { Chunk::Trailers(0) } else { Chunk::Data(size) };
+            }
+            Chunk::Data(remaining) => {
+                let take = (*remaining).min(rest.len());
+                request.body.extend_from_slice(&rest[..take]);
+                consumed += take;
+                *remaining -= take;
+                if *remaining > 0 {
+                    return Ok((consumed, false));
+                }
+                *chunk = Chunk::DataEnd;
+            }
+            Chunk::DataEnd => {
+                if rest.len() < 2 {
+                    return Ok((consumed, false));
+                }
+                if &rest[..2] != b"\r\n" {
+                    return Err(ParseError::BadRequest("chunk data not followed by CRLF"));
+                }
+                consumed += 2;
+                *chunk = Chunk::Size;
+            }
+            Chunk::Trailers(seen) => {
+                let Some(end) = find(rest, 0, b"\r\n") else {
+                    if *seen + rest.len() > MAX_HEAD_SIZE {
+                        return Err(ParseError::HeadTooLarge);
+                    }
+                    return Ok((consumed, false));
+                };
+                consumed += end + 2;
+                *seen += end + 2;
+                if end == 0 {
+                    return Ok((consumed, true));
+                }
+                if *seen > MAX_HEAD_SIZE {
+                    return Err(ParseError::HeadTooLarge);
+                }
+            }
+        }
+    }
+}
+
+// Hexadecimal chunk size, ignoring any chunk extensions after it
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    let digits = line.split(|&byte| byte == b';').next().unwrap_or(&[]);
+    let digits = std::str::from_utf8(digits).unwrap_or("").trim_end_matches([' ', '\t']);
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ParseError::BadRequest("invalid chunk size"));
+    }
+    usize::from_str_radix(digits, 16).map_err(|_| ParseError::PayloadTooLarge)
+}
+
+// Comma-separated values of every header with the given name
+fn header_tokens<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers.iter()
+        .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+fn find(haystack: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    haystack.get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+// A response, encoded in full once its handler has produced it
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+    
+    pub fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::new(status, "application/json", body),
+            Err(e) => Self::text(500, format!("Cannot encode response: {}", e)),
+        }
+    }
+    
+    pub fn text(status: u16, message: impl Into<String>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", message.into().into_bytes())
+    }
+    
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    
+    // Append the encoded response to `out`. The body is left out in answer to
+    // HEAD, though its length is still given.
+    pub fn encode(&self, version: Version, keep_alive: bool, head: bool, out: &mut Vec<u8>) {
+        let mut text = format!("{} {} {}\r\n", version.as_str(), self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            text.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        text.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        match (keep_alive, version) {
+            (false, _) => text.push_str("Connection: close\r\n"),
+            (true, Version::Http10) => text.push_str("Connection: keep-alive\r\n"),
+            (true, Version::Http11) => {}
+        }
+        text.push_str("\r\n");
+        
+        out.extend_from_slice(text.as_bytes());
+        if !head {
+            out.extend_from_slice(&self.body);
+        }
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed `input` in pieces of `step` bytes, as a socket might deliver it,
+    // dropping consumed bytes from the front as the server does
+    fn feed(parser: &mut Parser, input: &[u8], step: usize) -> Result<Vec<Request>, ParseError> {
+        let mut buffer = Vec::new();
+        let mut requests = Vec::new();
+        for piece in input.chunks(step) {
+            buffer.extend_from_slice(piece);
+            loop {
+                let (used, request) = parser.parse(&buffer)?;
+                buffer.drain(..used);
+                match request {
+                    Some(request) => requests.push(request),
+                    None => break,
+                }
+            }
+        }
+        assert!(buffer.is_empty(), "{} bytes left over", buffer.len());
+        Ok(requests)
+    }
+
+    #[test]
+    fn parses_a_head_split_across_reads() {
+        let input = b"POST /v1/models/resnet:predict HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        for step in [1, 2, 3, 7, 16] {
+            let mut parser = Parser::new(1024);
+            let requests = feed(&mut parser, input, step).unwrap();
+            assert_eq!(requests.len(), 1, "step {}", step);
+            let request = &requests[0];
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.path(), "/v1/models/resnet:predict");
+            assert_eq!(request.header("content-length"), Some("5"));
+            assert_eq!(request.body, b"hello");
+            assert!(!parser.in_progress());
+        }
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_split_header() {
+        let mut parser = Parser::new(1024);
+        assert_eq!(parser.parse(b"GET / HTTP/1.1\r\nHo").unwrap().0, 0);
+        assert_eq!(parser.parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r").unwrap().0, 0);
+        let (used, request) = parser.parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(used, 27);
+        assert_eq!(request.unwrap().header("HOST"), Some("a"));
+    }
+
+    #[test]
+    fn decodes_a_chunked_body() {
+        let input = b"POST /predict HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n1\r\n \r\n5\r\nworld\r\n0\r\nX-Trailer: dropped\r\n\r\n";
+        for step in [1, 4, input.len()] {
+            let requests = feed(&mut Parser::new(1024), input, step).unwrap();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].body, b"hello world");
+            assert_eq!(requests[0].header("x-trailer"), None);
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_chunks() {
+        let head = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let bad_size = [head.as_slice(), b"zz\r\n"].concat();
+        assert_eq!(
+            Parser::new(1024).parse(&bad_size).unwrap_err(),
+            ParseError::BadRequest("invalid chunk size")
+        );
+        let missing_crlf = [head.as_slice(), b"2\r\nabX\r\n"].concat();
+        assert_eq!(
+            Parser::new(1024).parse(&missing_crlf).unwrap_err(),
+            ParseError::BadRequest("chunk data not followed by CRLF")
+        );
+        let too_large = [head.as_slice(), b"401\r\n"].concat();
+        assert_eq!(Parser::new(1024).parse(&too_large).unwrap_err(), ParseError::PayloadTooLarge);
+    }
+
+    #[test]
+    fn refuses_an_oversized_content_length() {
+        let mut parser = Parser::new(1024);
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1025\r\n\r\n";
+        assert_eq!(parser.parse(input).unwrap_err(), ParseError::PayloadTooLarge);
+        assert_eq!(ParseError::PayloadTooLarge.status(), 413);
+
+        // One that does not fit in a usize is too large as well, not malformed
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n";
+        assert_eq!(Parser::new(1024).parse(input).unwrap_err(), ParseError::PayloadTooLarge);
+
+        // The limit itself is allowed
+        let input = [b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1024\r\n\r\n".as_slice(), &[b'x'; 1024]].concat();
+        let requests = feed(&mut Parser::new(1024), &input, 100).unwrap();
+        assert_eq!(requests[0].body.len(), 1024);
+    }
+
+    #[test]
+    fn refuses_ambiguous_lengths() {
+        let both = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(matches!(Parser::new(1024).parse(both), Err(ParseError::BadRequest(_))));
+        let conflicting = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\n";
+        assert_eq!(
+            Parser::new(1024).parse(conflicting).unwrap_err(),
+            ParseError::BadRequest("conflicting content-length values")
+        );
+    }
+
+    #[test]
+    fn parses_pipelined_requests_in_order() {
+        let input = b"GET /health HTTP/1.1\r\nHost: a\r\n\r\n\
+            POST /predict HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabc\
+            \r\n\
+            POST /predict HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n0\r\n\r\n\
+            GET /metrics HTTP/1.0\r\n\r\n";
+        for step in [1, 5, input.len()] {
+            let requests = feed(&mut Parser::new(1024), input, step).unwrap();
+            let summary: Vec<(&str, &[u8])> =
+                requests.iter().map(|request| (request.target.as_str(), request.body.as_slice())).collect();
+            assert_eq!(
+                summary,
+                [("/health", &b""[..]), ("/predict", b"abc"), ("/predict", b"de"), ("/metrics", b"")]
+            );
+            assert!(requests[2].keep_alive());
+            assert!(!requests[3].keep_alive());
+        }
+    }
+
+    #[test]
+    fn yields_one_pipelined_request_per_call() {
+        let input = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut parser = Parser::new(1024);
+        let (used, first) = parser.parse(input).unwrap();
+        assert_eq!(first.unwrap().target, "/a");
+        let (rest, second) = parser.parse(&input[used..]).unwrap();
+        assert_eq!(second.unwrap().target, "/b");
+        assert_eq!(used + rest, input.len());
+    }
+
+    #[test]
+    fn refuses_a_head_too_large() {
+        let mut input = b"GET / HTTP/1.1\r\nHost: a\r\nX-Padding: ".to_vec();
+        input.resize(MAX_HEAD_SIZE + 1, b'x');
+        assert_eq!(Parser::new(1024).parse(&input).unwrap_err(), ParseError::HeadTooLarge);
+    }
+
+    #[test]
+    fn reports_expect_continue_once() {
+        let mut parser = Parser::new(1024);
+        let input = b"POST / HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n";
+        let (used, request) = parser.parse(input).unwrap();
+        assert_eq!(used, input.len());
+        assert!(request.is_none());
+        assert!(parser.in_progress());
+        assert!(parser.take_continue());
+        assert!(!parser.take_continue());
+        let (used, request) = parser.parse(b"ok").unwrap();
+        assert_eq!((used, request.unwrap().body), (2, b"ok".to_vec()));
+    }
+
+    #[test]
+    fn encodes_a_response() {
+        let mut out = Vec::new();
+        Response::text(200, "ok").encode(Version::Http10, true, false, &mut out);
+        assert_eq!(
+            out,
+            b"HTTP/1.0 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 2\r\n\
+              Connection: keep-alive\r\n\r\nok"
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Structured access logs: one JSON object per request, a line each, on
+// standard output
+use std::{
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+// Header carrying the request ID, taken from the client when it sends one
+// and returned on every response so failures can be traced across services
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+#[derive(Serialize)]
+pub struct AccessLog<'a> {
+    pub timestamp: String,
+    pub request_id: &'a str,
+    pub protocol: &'static str,
+    pub method: &'a str,
+    pub path: &'a str,
+    // HTTP status, or gRPC status code
+    pub status: u16,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_in: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_out: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+impl<'a> AccessLog<'a> {
+    pub fn new(request_id: &'a str, protocol: &'static str, method: &'a str, path: &'a str, status: u16, elapsed: Duration) -> Self {
+        Self {
+            timestamp: timestamp(SystemTime::now()),
+            request_id,
+            protocol,
+            method,
+            path,
+            status,
+            duration_ms: elapsed.as_secs_f64() * 1000.0,
+            peer: None,
+            bytes_in: None,
+            bytes_out: None,
+            client: None,
+            model: None,
+            version: None,
+            error: None,
+        }
+    }
+    
+    pub fn write(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+        }
+    }
+}
+
+// The client's request ID if it is usable, otherwise a new one
+pub fn request_id(given: Option<&str>) -> String {
+    match given {
+        Some(id) if !id.is_empty() && id.len() <= 128 && id.bytes().all(|byte| byte.is_ascii_graphic()) => id.to_string(),
+        _ => format!("{:016x}", super::rand::random::<u64>()),
+    }
+}
+
+// RFC 3339 UTC time with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, rest) = (seconds / 86400, seconds % 86400);
+    
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rest / 3600, rest % 3600 / 60, rest % 60, since_epoch.subsec_millis()
+    )
+}
\ No newline at end of file
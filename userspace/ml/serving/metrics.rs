@@ -0,0 +1,301 @@
+// Server metrics, rendered at /metrics in the Prometheus text exposition
+// format
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+// Content type of the rendered metrics
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations per bucket, the last past every bound
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+    
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+    
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let bound = self.bounds.get(index).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+// What is tracked for each loaded model version
+#[derive(Clone)]
+struct ModelSeries {
+    latency: Histogram,
+    batch_size: Histogram,
+    successes: u64,
+    errors: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+impl ModelSeries {
+    fn new() -> Self {
+        Self {
+            latency: Histogram::new(LATENCY_BUCKETS),
+            batch_size: Histogram::new(BATCH_SIZE_BUCKETS),
+            successes: 0,
+            errors: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerMetrics {
+    started: Instant,
+    // Responses by protocol and status code; gRPC codes are by name
+    responses: BTreeMap<(&'static str, String), u64>,
+    // Time from a request arriving to its response, by protocol
+    request_duration: BTreeMap<&'static str, Histogram>,
+    // Predictions by model and version
+    models: BTreeMap<(String, String), ModelSeries>,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            responses: BTreeMap::new(),
+            request_duration: BTreeMap::new(),
+            models: BTreeMap::new(),
+        }
+    }
+    
+    pub fn observe_response(&mut self, protocol: &'static str, code: &str, elapsed: Duration) {
+        *self.responses.entry((protocol, code.to_string())).or_insert(0) += 1;
+        self.request_duration
+            .entry(protocol)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+    
+    pub fn observe_prediction(&mut self, model: &str, version: &str, elapsed: Duration, batch_size: usize, success: bool) {
+        let series = self.models.entry((model.to_string(), version.to_string())).or_insert_with(ModelSeries::new);
+        series.latency.observe(elapsed.as_secs_f64());
+        series.batch_size.observe(batch_size as f64);
+        if success {
+            series.successes += 1;
+        } else {
+            series.errors += 1;
+        }
+    }
+    
+    pub fn observe_cache(&mut self, model: &str, version: &str, hit: bool) {
+        let series = self.models.entry((model.to_string(), version.to_string())).or_insert_with(ModelSeries::new);
+        if hit {
+            series.cache_hits += 1;
+        } else {
+            series.cache_misses += 1;
+        }
+    }
+    
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        
+        header(&mut out, "model_server_uptime_seconds", "gauge", "Time since the server started.");
+        let _ = writeln!(out, "model_server_uptime_seconds {}", self.started.elapsed().as_secs_f64());
+        
+        header(&mut out, "model_server_responses_total", "counter", "Responses sent, by protocol and status code.");
+        for ((protocol, code), count) in &self.responses {
+            let _ = writeln!(out, "model_server_responses_total{{protocol=\"{}\",code=\"{}\"}} {}", protocol, escape(code), count);
+        }
+        
+        let name = "model_server_request_duration_seconds";
+        header(&mut out, name, "histogram", "Time from a request arriving to its response, by protocol.");
+        for (protocol, histogram) in &self.request_duration {
+            histogram.render(&mut out, name, &format!("protocol=\"{}\"", protocol));
+        }
+        
+        header(&mut out, "model_predictions_total", "counter", "Predictions run, by model, version and outcome.");
+        for ((model, version), series) in &self.models {
+            let labels = model_labels(model, version);
+            let _ = writeln!(out, "model_predictions_total{{{},outcome=\"success\"}} {}", labels, series.successes);
+            let _ = writeln!(out, "model_predictions_total{{{},outcome=\"error\"}} {}", labels, series.errors);
+        }
+        
+        header(&mut out, "model_cache_lookups_total", "counter", "Response cache lookups, by model, version and result.");
+        for ((model, version), series) in &self.models {
+            let labels = model_labels(model, version);
+            let _ = writeln!(out, "model_cache_lookups_total{{{},result=\"hit\"}} {}", labels, series.cache_hits);
+            let _ = writeln!(out, "model_cache_lookups_total{{{},result=\"miss\"}} {}", labels, series.cache_misses);
+        }
+        
+        let name = "model_prediction_duration_seconds";
+        header(&mut out, name, "histogram", "Time spent running predictions, by model and version.");
+        for ((model, version), series) in &self.models {
+            series.latency.render(&mut out, name, &model_labels(model, version));
+        }
+        
+        let name = "model_prediction_batch_size";
+        header(&mut out, name, "histogram", "Batch size of predictions, by model and version.");
+        for ((model, version), series) in &self.models {
+            series.batch_size.render(&mut out, name, &model_labels(model, version));
+        }
+        
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn model_labels(model: &str, version: &str) -> String {
+    format!("model=\"{}\",version=\"{}\"", escape(model), escape(version))
+}
+
+// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Sample lines of a rendering, without HELP and TYPE comments
+    fn samples(metrics: &ServerMetrics) -> Vec<String> {
+        metrics.render().lines().filter(|line| !line.starts_with('#')).map(str::to_string).collect()
+    }
+    
+    fn sample<'a>(samples: &'a [String], series: &str) -> Option<&'a str> {
+        samples.iter().find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+    }
+    
+    #[test]
+    fn describes_every_family_before_it_is_used() {
+        let mut metrics = ServerMetrics::new();
+        metrics.observe_response("http", "200", Duration::from_millis(3));
+        metrics.observe_prediction("m", "1", Duration::from_millis(3), 1, true);
+        let rendered = metrics.render();
+        
+        let mut described = Vec::new();
+        for line in rendered.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "histogram"].contains(&kind), "{}", line);
+                described.push(name.to_string());
+            } else if !line.starts_with("# HELP ") {
+                let name = line.split(['{', ' ']).next().unwrap();
+                let family = ["_bucket", "_sum", "_count"].iter().fold(name, |name, suffix| name.strip_suffix(suffix).unwrap_or(name));
+                assert!(described.iter().any(|known| known == name || known == family), "{} is not described", name);
+            }
+        }
+        assert!(rendered.ends_with('\n'));
+    }
+    
+    #[test]
+    fn counts_responses_by_protocol_and_code() {
+        let mut metrics = ServerMetrics::new();
+        metrics.observe_response("http", "200", Duration::from_millis(1));
+        metrics.observe_response("http", "200", Duration::from_millis(1));
+        metrics.observe_response("http", "404", Duration::from_millis(1));
+        metrics.observe_response("grpc", "NotFound", Duration::from_millis(1));
+        let samples = samples(&metrics);
+        assert_eq!(sample(&samples, "model_server_responses_total{protocol=\"http\",code=\"200\"}"), Some("2"));
+        assert_eq!(sample(&samples, "model_server_responses_total{protocol=\"http\",code=\"404\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_server_responses_total{protocol=\"grpc\",code=\"NotFound\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_server_request_duration_seconds_count{protocol=\"http\"}"), Some("3"));
+    }
+    
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let mut metrics = ServerMetrics::new();
+        for millis in [1, 4, 4, 40, 20_000] {
+            metrics.observe_prediction("m", "1", Duration::from_millis(millis), 3, true);
+        }
+        let samples = samples(&metrics);
+        let bucket = |le: &str| {
+            sample(&samples, &format!("model_prediction_duration_seconds_bucket{{model=\"m\",version=\"1\",le=\"{}\"}}", le))
+        };
+        // Bounds are inclusive
+        assert_eq!(bucket("0.001"), Some("1"));
+        assert_eq!(bucket("0.0025"), Some("1"));
+        assert_eq!(bucket("0.005"), Some("3"));
+        assert_eq!(bucket("0.05"), Some("4"));
+        assert_eq!(bucket("10"), Some("4"));
+        assert_eq!(bucket("+Inf"), Some("5"));
+        assert_eq!(sample(&samples, "model_prediction_duration_seconds_count{model=\"m\",version=\"1\"}"), Some("5"));
+        let sum: f64 = sample(&samples, "model_prediction_duration_seconds_sum{model=\"m\",version=\"1\"}").unwrap().parse().unwrap();
+        assert!((sum - 20.049).abs() < 1e-9, "{}", sum);
+        
+        // Batch sizes go in buckets of their own
+        assert_eq!(
+            sample(&samples, "model_prediction_batch_size_bucket{model=\"m\",version=\"1\",le=\"2\"}"),
+            Some("0")
+        );
+        assert_eq!(
+            sample(&samples, "model_prediction_batch_size_bucket{model=\"m\",version=\"1\",le=\"4\"}"),
+            Some("5")
+        );
+    }
+    
+    #[test]
+    fn counts_outcomes_and_cache_lookups_per_version() {
+        let mut metrics = ServerMetrics::new();
+        metrics.observe_prediction("m", "1", Duration::ZERO, 1, true);
+        metrics.observe_prediction("m", "1", Duration::ZERO, 1, false);
+        metrics.observe_prediction("m", "2", Duration::ZERO, 1, true);
+        metrics.observe_cache("m", "1", true);
+        metrics.observe_cache("m", "1", false);
+        metrics.observe_cache("m", "1", false);
+        let samples = samples(&metrics);
+        assert_eq!(sample(&samples, "model_predictions_total{model=\"m\",version=\"1\",outcome=\"success\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_predictions_total{model=\"m\",version=\"1\",outcome=\"error\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_predictions_total{model=\"m\",version=\"2\",outcome=\"success\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_cache_lookups_total{model=\"m\",version=\"1\",result=\"hit\"}"), Some("1"));
+        assert_eq!(sample(&samples, "model_cache_lookups_total{model=\"m\",version=\"1\",result=\"miss\"}"), Some("2"));
+        assert_eq!(sample(&samples, "model_cache_lookups_total{model=\"m\",version=\"2\",result=\"miss\"}"), Some("0"));
+    }
+    
+    #[test]
+    fn escapes_label_values() {
+        let mut metrics = ServerMetrics::new();
+        metrics.observe_prediction("a\"b\\c\nd", "1", Duration::ZERO, 1, true);
+        let samples = samples(&metrics);
+        assert_eq!(
+            sample(&samples, "model_predictions_total{model=\"a\\\"b\\\\c\\nd\",version=\"1\",outcome=\"success\"}"),
+            Some("1")
+        );
+        assert!(samples.iter().all(|line| !line.is_empty()));
+    }
+}
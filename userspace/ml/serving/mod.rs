@@ -1,21 +1,48 @@
 // ML model serving infrastructure
 use std::{
     sync::{Arc, RwLock, Mutex},
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
+    fmt,
     time::{Duration, Instant},
     thread,
-    net::{TcpListener, TcpStream},
-    io::{Read, Write},
+    net::{Shutdown, SocketAddr},
+    io::{self, ErrorKind, Read, Write},
+    path::PathBuf,
+    sync::mpsc,
 };
 
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Registry, Token, Waker,
+};
 use serde::{Deserialize, Serialize};
-use serde_json;
+
+mod auth;
+mod cache;
+mod grpc;
+mod http;
+mod logging;
+mod metrics;
+pub mod onnx;
+mod repository;
+
+pub use self::auth::{AuthConfig, ClientConfig, RateLimit};
+pub use self::metrics::ServerMetrics;
+
+use self::auth::{AccessControl, Caller, Denied};
+use self::cache::ResponseCache;
+use self::http::{Parser, Request, Response, Version};
+use self::logging::{AccessLog, REQUEST_ID_HEADER};
+use self::repository::RepositoryWatcher;
 
 // Model server for serving ML models
 pub struct ModelServer {
     models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
     config: ServerConfig,
     metrics: Arc<Mutex<ServerMetrics>>,
+    // Which version of each model takes unversioned requests
+    traffic: Arc<Mutex<ABTestManager>>,
+    access: Arc<AccessControl>,
     thread_pool: ThreadPool,
 }
 
@@ -25,7 +52,29 @@ pub struct ModelEndpoint {
     version: String,
     model: Arc<RwLock<Box<dyn Model>>>,
     config: EndpointConfig,
-    metrics: EndpointMetrics,
+    metrics: Mutex<EndpointMetrics>,
+    cache: Mutex<ResponseCache>,
+}
+
+impl ModelEndpoint {
+    fn new(name: String, version: String, model: Box<dyn Model>) -> Self {
+        let config = EndpointConfig::default();
+        Self {
+            name,
+            version,
+            model: Arc::new(RwLock::new(model)),
+            cache: Mutex::new(ResponseCache::new(config.cache_ttl, config.cache_max_entries, config.cache_max_bytes)),
+            config,
+            metrics: Mutex::new(EndpointMetrics::new()),
+        }
+    }
+    
+    // Swap in a new model. Requests under way keep the old one until they
+    // finish, and nothing it answered is served from the cache.
+    fn replace_model(&mut self, model: Box<dyn Model>) {
+        self.model = Arc::new(RwLock::new(model));
+        self.cache.lock().unwrap().clear();
+    }
 }
 
 // Server configuration
@@ -35,11 +84,29 @@ pub struct ServerConfig {
     pub port: u16,
     pub num_workers: usize,
     pub max_connections: usize,
+    // How long a connection may sit idle, or take to send a request
     pub request_timeout: Duration,
+    // Largest request body accepted, in bytes
+    pub max_body_size: usize,
+    // Port for the gRPC inference protocol, beside the HTTP one on `port`
+    pub grpc_port: Option<u16>,
     pub enable_metrics: bool,
+    // Write a JSON line to standard output for every request
+    pub access_log: bool,
     pub enable_health_check: bool,
     pub enable_model_versioning: bool,
     pub enable_a_b_testing: bool,
+    // Directory to load models from and watch for new versions
+    pub model_repository: Option<PathBuf>,
+    pub repository_poll_interval: Duration,
+    // Share of unversioned traffic a version found in the repository takes
+    // until it is promoted or rolled back
+    pub canary_traffic: f32,
+    // Clients and the models each may use. Without it every request is
+    // served, which is only safe on localhost.
+    pub auth: Option<AuthConfig>,
+    // Requests per client, unless the client has a limit of its own
+    pub rate_limit: Option<RateLimit>,
 }
 
 impl Default for ServerConfig {
@@ -50,10 +117,18 @@ impl Default for ServerConfig {
             num_workers: 4,
             max_connections: 1000,
             request_timeout: Duration::from_secs(30),
+            max_body_size: 64 * 1024 * 1024,
+            grpc_port: Some(8081),
             enable_metrics: true,
+            access_log: true,
             enable_health_check: true,
             enable_model_versioning: true,
             enable_a_b_testing: false,
+            model_repository: None,
+            repository_poll_interval: Duration::from_secs(5),
+            canary_traffic: 0.1,
+            auth: None,
+            rate_limit: None,
         }
     }
 }
@@ -65,10 +140,28 @@ pub struct EndpointConfig {
     pub max_batch_delay: Duration,
     pub enable_caching: bool,
     pub cache_ttl: Duration,
+    // Limits of the response cache
+    pub cache_max_entries: usize,
+    pub cache_max_bytes: usize,
     pub preprocessing: Option<PreprocessingConfig>,
     pub postprocessing: Option<PostprocessingConfig>,
 }
 
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 32,
+            max_batch_delay: Duration::from_millis(10),
+            enable_caching: true,
+            cache_ttl: Duration::from_secs(60),
+            cache_max_entries: 10_000,
+            cache_max_bytes: 64 * 1024 * 1024,
+            preprocessing: None,
+            postprocessing: None,
+        }
+    }
+}
+
 // Preprocessing configuration
 #[derive(Clone)]
 pub struct PreprocessingConfig {
@@ -171,229 +264,832 @@ pub enum ModelError {
     VersionNotFound,
 }
 
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModelError::InvalidInput(message) => write!(f, "invalid input: {}", message),
+            ModelError::InferenceError(message) => write!(f, "inference failed: {}", message),
+            ModelError::TimeoutError => write!(f, "inference timed out"),
+            ModelError::ModelNotFound => write!(f, "model not found"),
+            ModelError::VersionNotFound => write!(f, "model version not found"),
+        }
+    }
+}
+
 impl ModelServer {
     pub fn new(config: ServerConfig) -> Self {
         Self {
             models: Arc::new(RwLock::new(HashMap::new())),
             metrics: Arc::new(Mutex::new(ServerMetrics::new())),
+            traffic: Arc::new(Mutex::new(ABTestManager::new())),
+            access: Arc::new(AccessControl::new(config.auth.clone(), config.rate_limit)),
             thread_pool: ThreadPool::new(config.num_workers),
             config,
         }
     }
     
     pub fn register_model(&self, name: String, version: String, model: Box<dyn Model>) -> Result<(), ServerError> {
-        let endpoint = ModelEndpoint {
-            name: name.clone(),
-            version: version.clone(),
-            model: Arc::new(RwLock::new(model)),
-            config: EndpointConfig {
-                batch_size: 32,
-                max_batch_delay: Duration::from_millis(10),
-                enable_caching: true,
-                cache_ttl: Duration::from_secs(60),
-                preprocessing: None,
-                postprocessing: None,
-            },
-            metrics: EndpointMetrics::new(),
-        };
+        let endpoint = ModelEndpoint::new(name.clone(), version.clone(), model);
         
         let mut models = self.models.write().unwrap();
         let key = format!("{}:{}", name, version);
         models.insert(key, endpoint);
         
+        // The first version of a model takes its unversioned traffic
+        let mut traffic = self.traffic.lock().unwrap();
+        if traffic.rollout(&name).is_none() {
+            traffic.create_experiment(name, version.clone(), version, 1.0);
+        }
+        
         Ok(())
     }
     
+    // Change the configuration of a registered model version
+    pub fn configure_endpoint(&self, name: &str, version: &str, config: EndpointConfig) -> Result<(), ServerError> {
+        let mut models = self.models.write().unwrap();
+        let endpoint = models.get_mut(&model_key(name, Some(version)))
+            .ok_or_else(|| ServerError::ConfigError(format!("model {} has no version {}", name, version)))?;
+        
+        let mut cache = endpoint.cache.lock().unwrap();
+        if config.enable_caching {
+            cache.configure(config.cache_ttl, config.cache_max_entries, config.cache_max_bytes);
+        } else {
+            cache.clear();
+        }
+        drop(cache);
+        endpoint.config = config;
+        Ok(())
+    }
+    
+    // Serve until the event loop fails. One thread multiplexes every
+    // connection; predictions run on the worker pool and their responses are
+    // handed back through a channel and a waker.
     pub fn start(&self) -> Result<(), ServerError> {
         let addr = format!("{}:{}", self.config.host, self.config.port);
-        let listener = TcpListener::bind(&addr)
+        let mut listener = std::net::TcpListener::bind(&addr)
+            .and_then(|listener| {
+                listener.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(listener))
+            })
             .map_err(|e| ServerError::BindError(e.to_string()))?;
         
+        let mut poll = Poll::new().map_err(|e| ServerError::IoError(e.to_string()))?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)
+            .map_err(|e| ServerError::IoError(e.to_string()))?;
+        let waker = Waker::new(poll.registry(), WAKER).map_err(|e| ServerError::IoError(e.to_string()))?;
+        let (replies, finished) = mpsc::channel();
+        let context = Context {
+            models: &self.models,
+            metrics: &self.metrics,
+            traffic: &self.traffic,
+            access: &self.access,
+            config: &self.config,
+            thread_pool: &self.thread_pool,
+            replies,
+            waker: Arc::new(waker),
+        };
+        
+        if let Some(root) = &self.config.model_repository {
+            let mut watcher = RepositoryWatcher::new(
+                root.clone(),
+                Arc::clone(&self.models),
+                Arc::clone(&self.traffic),
+                self.config.canary_traffic,
+            );
+            watcher.scan()?;
+            watcher.spawn(self.config.repository_poll_interval);
+            println!("Watching model repository {}", root.display());
+        }
+        
+        if let Some(port) = self.config.grpc_port {
+            let grpc_addr = format!("{}:{}", self.config.host, port);
+            grpc::spawn(
+                &grpc_addr,
+                Arc::clone(&self.models),
+                Arc::clone(&self.traffic),
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.access),
+                &self.config,
+            )?;
+            println!("gRPC inference endpoint listening on {}", grpc_addr);
+        }
+        
         println!("Model server listening on {}", addr);
         
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let models = Arc::clone(&self.models);
-                    let metrics = Arc::clone(&self.metrics);
-                    
-                    self.thread_pool.execute(move || {
-                        handle_client(stream, models, metrics);
-                    });
-                },
-                Err(e) => {
-                    eprintln!("Connection error: {}", e);
+        let mut connections: HashMap<Token, Connection> = HashMap::new();
+        let mut next_token = FIRST_CONNECTION;
+        let mut events = Events::with_capacity(1024);
+        // Wake up regularly to drop connections that have timed out
+        let tick = self.config.request_timeout.min(Duration::from_secs(1));
+        
+        loop {
+            if let Err(e) = poll.poll(&mut events, Some(tick)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
                 }
+                return Err(ServerError::IoError(e.to_string()));
             }
+            
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => loop {
+                        let (mut stream, peer) = match listener.accept() {
+                            Ok(accepted) => accepted,
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                eprintln!("Connection error: {}", e);
+                                break;
+                            }
+                        };
+                        
+                        if connections.len() >= self.config.max_connections {
+                            // Best effort: a fresh socket has room for this
+                            let mut output = Vec::new();
+                            Response::text(503, "Too many connections").encode(Version::Http11, false, false, &mut output);
+                            let _ = stream.write(&output);
+                            continue;
+                        }
+                        
+                        let token = Token(next_token);
+                        next_token += 1;
+                        match poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                            Ok(()) => {
+                                connections.insert(token, Connection::new(stream, peer, self.config.max_body_size));
+                            },
+                            Err(e) => eprintln!("Connection error: {}", e),
+                        }
+                    },
+                    WAKER => {
+                        for reply in finished.try_iter() {
+                            // The connection may have gone while its request ran
+                            let token = reply.token;
+                            if let Some(connection) = connections.get_mut(&token) {
+                                connection.complete(reply, &context);
+                                drive(&mut connections, token, &context, poll.registry());
+                            }
+                        }
+                    },
+                    token => drive(&mut connections, token, &context, poll.registry()),
+                }
+            }
+            
+            let now = Instant::now();
+            connections.retain(|_, connection| {
+                if !connection.expired(now, self.config.request_timeout) {
+                    return true;
+                }
+                connection.time_out(&context);
+                let _ = poll.registry().deregister(&mut connection.stream);
+                false
+            });
         }
-        
-        Ok(())
     }
     
+    // A snapshot of the metrics served at /metrics
     pub fn get_metrics(&self) -> ServerMetrics {
         self.metrics.lock().unwrap().clone()
     }
 }
 
-fn handle_client(
-    mut stream: TcpStream,
-    models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
-    metrics: Arc<Mutex<ServerMetrics>>,
-) {
-    let mut buffer = [0; 4096];
+// Event loop tokens. Connections are numbered upwards from FIRST_CONNECTION
+// and never reused, so a late response cannot reach a newer connection.
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+// Received bytes left unparsed, and encoded bytes left unsent, beyond which a
+// connection stops taking in more until its peer catches up
+const READ_BUFFER_LIMIT: usize = 64 * 1024;
+const WRITE_BUFFER_LIMIT: usize = 1024 * 1024;
+
+// What request handling needs from the event loop
+struct Context<'a> {
+    models: &'a Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+    metrics: &'a Arc<Mutex<ServerMetrics>>,
+    traffic: &'a Arc<Mutex<ABTestManager>>,
+    access: &'a AccessControl,
+    config: &'a ServerConfig,
+    thread_pool: &'a ThreadPool,
+    replies: mpsc::Sender<Reply>,
+    waker: Arc<Waker>,
+}
+
+// A prediction's response, handed back to the event loop
+struct Reply {
+    token: Token,
+    response: Response,
+    // For the access log
+    version: Option<String>,
+    error: Option<String>,
+}
+
+// Where a request goes
+enum Route {
+    Ready(Response),
+    Predict(PredictRequest),
+}
+
+// Paths served without credentials or rate limiting, for load balancers
+const PUBLIC_PATHS: &[&str] = &["/health"];
+
+fn route(request: &Request, caller: &Caller, context: &Context) -> Route {
+    let config = context.config;
+    match (request.method.as_str(), request.path()) {
+        ("POST", "/predict") => match serde_json::from_slice::<PredictRequest>(&request.body) {
+            Ok(predict) if !context.access.may_use(caller, &predict.model_name) => {
+                Route::Ready(Response::text(403, format!("Not allowed to use model {}", predict.model_name)))
+            },
+            Ok(predict) => Route::Predict(predict),
+            Err(e) => Route::Ready(Response::text(400, format!("Invalid request: {}", e))),
+        },
+        (_, "/predict") => Route::Ready(Response::text(405, "Method not allowed").with_header("Allow", "POST")),
+        ("GET" | "HEAD", "/health") if config.enable_health_check => {
+            Route::Ready(Response::text(200, "OK"))
+        },
+        ("GET" | "HEAD", "/metrics") if config.enable_metrics => {
+            let text = context.metrics.lock().unwrap().render();
+            Route::Ready(Response::new(200, metrics::CONTENT_TYPE, text.into_bytes()))
+        },
+        (_, "/health") if config.enable_health_check => {
+            Route::Ready(Response::text(405, "Method not allowed").with_header("Allow", "GET, HEAD"))
+        },
+        (_, "/metrics") if config.enable_metrics => {
+            Route::Ready(Response::text(405, "Method not allowed").with_header("Allow", "GET, HEAD"))
+        },
+        (_, path) if config.enable_model_versioning && path.starts_with("/admin/models") && !caller.admin => {
+            Route::Ready(Response::text(403, "Administrator access required"))
+        },
+        (method, path) if config.enable_model_versioning && path.starts_with("/admin/models") => {
+            Route::Ready(admin(method, &path["/admin/models".len()..], &request.body, context))
+        },
+        _ => Route::Ready(Response::text(404, "Not found")),
+    }
+}
+
+// Body of the rollout admin requests; both fields are optional
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct RolloutRequest {
+    version: Option<String>,
+    // Share of unversioned traffic for the canary, from 0 to 1
+    traffic: Option<f32>,
+}
+
+// Rollout administration:
+//
+//   GET  /admin/models                  versions, rollouts and per-version metrics
+//   POST /admin/models/{name}/canary    send a share of traffic to a version
+//   POST /admin/models/{name}/promote   make the canary, or a version, stable
+//   POST /admin/models/{name}/rollback  drop the canary, or restore the last stable version
+fn admin(method: &str, path: &str, body: &[u8], context: &Context) -> Response {
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    let (name, action) = match (method, segments.as_slice()) {
+        ("GET" | "HEAD", []) => return Response::json(200, &model_statuses(context)),
+        (_, []) => return Response::text(405, "Method not allowed").with_header("Allow", "GET, HEAD"),
+        ("POST", [name, action @ ("canary" | "promote" | "rollback")]) => (*name, *action),
+        (_, [_, "canary" | "promote" | "rollback"]) => {
+            return Response::text(405, "Method not allowed").with_header("Allow", "POST");
+        },
+        _ => return Response::text(404, "Not found"),
+    };
     
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            let request_str = String::from_utf8_lossy(&buffer[..size]);
-            
-            // Parse HTTP request (simplified)
-            if let Some(body_start) = request_str.find("\r\n\r\n") {
-                let body = &request_str[body_start + 4..];
-                
-                // Parse JSON request
-                match serde_json::from_str::<PredictRequest>(body) {
-                    Ok(request) => {
-                        // Process prediction
-                        let response = process_prediction(request, models, metrics);
-                        
-                        // Send response
-                        let response_json = serde_json::to_string(&response).unwrap();
-                        let http_response = format!(
-                            "HTTP/1.1 200 OK\r\n\
-                             Content-Type: application/json\r\n\
-                             Content-Length: {}\r\n\
-                             \r\n\
-                             {}",
-                            response_json.len(),
-                            response_json
-                        );
-                        
-                        let _ = stream.write_all(http_response.as_bytes());
-                    },
-                    Err(e) => {
-                        let error_response = format!(
-                            "HTTP/1.1 400 Bad Request\r\n\
-                             Content-Type: text/plain\r\n\
-                             \r\n\
-                             Invalid request: {}",
-                            e
-                        );
-                        let _ = stream.write_all(error_response.as_bytes());
+    let request: RolloutRequest = if body.is_empty() {
+        RolloutRequest::default()
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return Response::text(400, format!("Invalid request: {}", e)),
+        }
+    };
+    if request.traffic.is_some_and(|traffic| !(0.0..=1.0).contains(&traffic)) {
+        return Response::text(400, "traffic must be between 0 and 1");
+    }
+    
+    // The registry is locked before the traffic manager, as everywhere else
+    let models = context.models.read().unwrap();
+    if let Some(version) = &request.version {
+        if !models.contains_key(&model_key(name, Some(version))) {
+            return Response::text(404, format!("Model {} has no version {}", name, version));
+        }
+    }
+    let mut traffic = context.traffic.lock().unwrap();
+    let Some(rollout) = traffic.rollout(name) else {
+        return Response::text(404, format!("Model {} not found", name));
+    };
+    
+    let changed = match action {
+        "canary" => match (request.version, request.traffic) {
+            (Some(version), traffic_share) => {
+                traffic.start_canary(name, version, traffic_share.unwrap_or(context.config.canary_traffic));
+                true
+            },
+            (None, Some(traffic_share)) if rollout.canary.is_some() => traffic.set_traffic_split(name, 1.0 - traffic_share),
+            (None, Some(_)) => return Response::text(409, format!("Model {} has no canary", name)),
+            (None, None) => return Response::text(400, "A version or a traffic share is required"),
+        },
+        "promote" => match request.version {
+            Some(version) if version == rollout.stable => {
+                return Response::text(409, format!("Version {} of model {} is already stable", version, name));
+            },
+            Some(version) => {
+                traffic.start_canary(name, version, 1.0);
+                traffic.promote(name).is_some()
+            },
+            None => traffic.promote(name).is_some(),
+        },
+        _ => traffic.rollback(name).is_some(),
+    };
+    if !changed {
+        return Response::text(409, format!("Nothing to {} for model {}", action, name));
+    }
+    
+    let rollout = traffic.rollout(name);
+    println!("Model {} rollout: {}", name, action);
+    Response::json(200, &rollout)
+}
+
+// Every model with its rollout and the metrics of each loaded version
+fn model_statuses(context: &Context) -> serde_json::Value {
+    let models = context.models.read().unwrap();
+    let traffic = context.traffic.lock().unwrap();
+    
+    let mut names: Vec<&str> = models.values().map(|endpoint| endpoint.name.as_str()).collect();
+    names.sort_unstable();
+    names.dedup();
+    let statuses: Vec<serde_json::Value> = names.into_iter()
+        .map(|name| {
+            let mut versions: Vec<&ModelEndpoint> = models.values().filter(|endpoint| endpoint.name == name).collect();
+            versions.sort_by(|a, b| repository::compare_versions(&a.version, &b.version));
+            let versions: Vec<serde_json::Value> = versions.into_iter()
+                .map(|endpoint| serde_json::json!({
+                    "version": endpoint.version,
+                    "metrics": *endpoint.metrics.lock().unwrap(),
+                }))
+                .collect();
+            serde_json::json!({
+                "name": name,
+                "rollout": traffic.rollout(name),
+                "versions": versions,
+            })
+        })
+        .collect();
+    serde_json::json!({ "models": statuses })
+}
+
+// Drive a connection after an event, dropping it once it is done with
+fn drive(connections: &mut HashMap<Token, Connection>, token: Token, context: &Context, registry: &Registry) {
+    let Some(connection) = connections.get_mut(&token) else {
+        return;
+    };
+    if !connection.drive(token, context) {
+        let _ = registry.deregister(&mut connection.stream);
+        let _ = connection.stream.shutdown(Shutdown::Write);
+        connections.remove(&token);
+    }
+}
+
+// How to answer the request being handled, and what to log about it
+struct Pending {
+    version: Version,
+    keep_alive: bool,
+    head: bool,
+    started: Instant,
+    request_id: String,
+    method: String,
+    path: String,
+    bytes_in: usize,
+    client: Option<String>,
+    model: Option<String>,
+    model_version: Option<String>,
+    error: Option<String>,
+}
+
+// A client connection. Requests on it are answered one at a time and in
+// order; pipelined requests wait in `input` until the one before is answered.
+struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+    parser: Parser,
+    // Received and not yet parsed
+    input: Vec<u8>,
+    // Encoded and not yet sent
+    output: Vec<u8>,
+    pending: Option<Pending>,
+    // No more requests are taken; the connection ends once output is sent
+    closing: bool,
+    // The peer has finished sending
+    eof: bool,
+    last_active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: SocketAddr, max_body_size: usize) -> Self {
+        Self {
+            stream,
+            peer,
+            parser: Parser::new(max_body_size),
+            input: Vec::new(),
+            output: Vec::new(),
+            pending: None,
+            closing: false,
+            eof: false,
+            last_active: Instant::now(),
+        }
+    }
+    
+    // Read, parse, dispatch and write until nothing more can happen without
+    // waiting. Returns false once the connection should be dropped.
+    fn drive(&mut self, token: Token, context: &Context) -> bool {
+        loop {
+            if self.fill().is_err() {
+                return false;
+            }
+            let progressed = self.process(token, context);
+            if self.flush().is_err() {
+                return false;
+            }
+            if !progressed {
+                break;
+            }
+        }
+        
+        // A request cut short by the peer can never be completed
+        let idle = self.output.is_empty() && self.pending.is_none();
+        !(idle && (self.closing || self.eof))
+    }
+    
+    // Take in whatever has arrived, up to READ_BUFFER_LIMIT unparsed bytes
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 16 * 1024];
+        while !self.eof && !self.closing && self.input.len() < READ_BUFFER_LIMIT {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.eof = true,
+                Ok(size) => {
+                    self.input.extend_from_slice(&buffer[..size]);
+                    self.last_active = Instant::now();
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    
+    // Parse and dispatch requests until one is waiting on the workers.
+    // Returns whether anything was consumed or queued.
+    fn process(&mut self, token: Token, context: &Context) -> bool {
+        let mut progressed = false;
+        while self.pending.is_none() && !self.closing && self.output.len() < WRITE_BUFFER_LIMIT {
+            match self.parser.parse(&self.input) {
+                Ok((consumed, request)) => {
+                    self.input.drain(..consumed);
+                    progressed |= consumed > 0;
+                    if self.parser.take_continue() {
+                        self.output.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                        progressed = true;
                     }
+                    match request {
+                        Some(request) => self.dispatch(token, request, context),
+                        None => break,
+                    }
+                },
+                Err(e) => {
+                    let response = Response::text(e.status(), e.to_string());
+                    self.reject(response, &e.to_string(), context);
+                    self.closing = true;
+                    self.input.clear();
+                    progressed = true;
                 }
             }
-        },
-        Err(e) => {
-            eprintln!("Failed to read from stream: {}", e);
         }
+        progressed
+    }
+    
+    fn dispatch(&mut self, token: Token, request: Request, context: &Context) {
+        self.pending = Some(Pending {
+            version: request.version,
+            keep_alive: request.keep_alive(),
+            head: request.method == "HEAD",
+            started: Instant::now(),
+            request_id: logging::request_id(request.header(REQUEST_ID_HEADER)),
+            method: request.method.clone(),
+            path: request.path().to_string(),
+            bytes_in: request.body.len(),
+            client: None,
+            model: None,
+            model_version: None,
+            error: None,
+        });
+        
+        let caller = if PUBLIC_PATHS.contains(&request.path()) {
+            Caller { client: None, admin: false }
+        } else {
+            let admitted = context.access.admit(
+                request.header(auth::API_KEY_HEADER),
+                request.header(auth::AUTHORIZATION_HEADER),
+                Some(self.peer.ip()),
+                Instant::now(),
+            );
+            match admitted {
+                Ok(caller) => caller,
+                Err(denied) => {
+                    if let Some(pending) = self.pending.as_mut() {
+                        pending.error = Some(denied.to_string());
+                    }
+                    self.finish(denied_response(&denied), context);
+                    return;
+                }
+            }
+        };
+        if let Some(pending) = self.pending.as_mut() {
+            pending.client = caller.client.clone();
+        }
+        
+        match route(&request, &caller, context) {
+            Route::Ready(response) => self.finish(response, context),
+            Route::Predict(predict) => {
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.model = Some(predict.model_name.clone());
+                }
+                let models = Arc::clone(context.models);
+                let traffic = Arc::clone(context.traffic);
+                let metrics = Arc::clone(context.metrics);
+                let replies = context.replies.clone();
+                let waker = Arc::clone(&context.waker);
+                
+                context.thread_pool.execute(move || {
+                    let (response, error) = process_prediction(predict, models, traffic, metrics);
+                    let reply = Reply {
+                        token,
+                        version: error.is_none().then(|| response.model_version.clone()),
+                        response: Response::json(200, &response),
+                        error,
+                    };
+                    if replies.send(reply).is_ok() {
+                        let _ = waker.wake();
+                    }
+                });
+            }
+        }
+    }
+    
+    // Queue the response to a prediction
+    fn complete(&mut self, reply: Reply, context: &Context) {
+        if let Some(pending) = self.pending.as_mut() {
+            pending.model_version = reply.version;
+            pending.error = reply.error;
+        }
+        self.finish(reply.response, context);
+    }
+    
+    // Queue the response to the pending request
+    fn finish(&mut self, response: Response, context: &Context) {
+        if let Some(pending) = self.pending.take() {
+            let response = response.with_header("X-Request-Id", &pending.request_id);
+            response.encode(pending.version, pending.keep_alive, pending.head, &mut self.output);
+            self.closing |= !pending.keep_alive;
+            self.last_active = Instant::now();
+            
+            let elapsed = pending.started.elapsed();
+            context.metrics.lock().unwrap().observe_response("http", &response.status.to_string(), elapsed);
+            if context.config.access_log {
+                let mut log = AccessLog::new(&pending.request_id, "http", &pending.method, &pending.path, response.status, elapsed);
+                log.peer = Some(self.peer.to_string());
+                log.bytes_in = Some(pending.bytes_in);
+                log.bytes_out = Some(response.body.len());
+                log.client = pending.client.as_deref();
+                log.model = pending.model.as_deref();
+                log.version = pending.model_version.as_deref();
+                log.error = pending.error.as_deref();
+                log.write();
+            }
+        }
+    }
+    
+    // Answer a request that could not be read, and close
+    fn reject(&mut self, response: Response, reason: &str, context: &Context) {
+        let request_id = logging::request_id(None);
+        let response = response.with_header("X-Request-Id", &request_id);
+        response.encode(Version::Http11, false, false, &mut self.output);
+        
+        context.metrics.lock().unwrap().observe_response("http", &response.status.to_string(), Duration::ZERO);
+        if context.config.access_log {
+            let mut log = AccessLog::new(&request_id, "http", "-", "-", response.status, Duration::ZERO);
+            log.peer = Some(self.peer.to_string());
+            log.error = Some(reason);
+            log.write();
+        }
+    }
+    
+    // Send as much of the output as the socket will take
+    fn flush(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => written += size,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if written > 0 {
+            self.output.drain(..written);
+            self.last_active = Instant::now();
+        }
+        Ok(())
+    }
+    
+    // Whether the connection has gone without progress for too long. Time
+    // spent waiting on a prediction does not count.
+    fn expired(&self, now: Instant, timeout: Duration) -> bool {
+        self.pending.is_none() && now.duration_since(self.last_active) > timeout
+    }
+    
+    // Tell a client that stalled part way through a request, then hang up
+    fn time_out(&mut self, context: &Context) {
+        if self.output.is_empty() && (self.parser.in_progress() || !self.input.is_empty()) {
+            self.reject(Response::text(408, "Request timed out"), "request timed out", context);
+            let _ = self.flush();
+        }
+        let _ = self.stream.shutdown(Shutdown::Write);
+    }
+}
+
+fn denied_response(denied: &Denied) -> Response {
+    let response = match denied {
+        Denied::MissingCredentials | Denied::InvalidCredentials => {
+            Response::text(401, denied.to_string()).with_header("WWW-Authenticate", "Bearer realm=\"model-server\"")
+        },
+        Denied::RateLimited(_) => Response::text(429, denied.to_string()),
+    };
+    match denied.retry_after() {
+        Some(seconds) => response.with_header("Retry-After", &seconds.to_string()),
+        None => response,
     }
 }
 
 fn process_prediction(
     request: PredictRequest,
     models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+    traffic: Arc<Mutex<ABTestManager>>,
     metrics: Arc<Mutex<ServerMetrics>>,
-) -> PredictResponse {
+) -> (PredictResponse, Option<String>) {
+    match predict(&request, &models, &traffic, &metrics) {
+        Ok(response) => (response, None),
+        Err(error) => {
+            // Return error response
+            let model_version = match error {
+                ModelError::ModelNotFound | ModelError::VersionNotFound => "not_found",
+                _ => "error",
+            };
+            let response = PredictResponse {
+                id: request.id,
+                model_name: request.model_name,
+                model_version: model_version.to_string(),
+                outputs: HashMap::new(),
+                metadata: None,
+            };
+            (response, Some(error.to_string()))
+        }
+    }
+}
+
+// Key of a model version in the registry; no version means the latest
+fn model_key(name: &str, version: Option<&str>) -> String {
+    format!("{}:{}", name, version.unwrap_or("latest"))
+}
+
+// Key of the version to serve a request from: the one it names, otherwise
+// the one the model's rollout sends it to
+fn resolve(name: &str, version: Option<&str>, traffic: &Mutex<ABTestManager>) -> String {
+    match version {
+        Some(version) => model_key(name, Some(version)),
+        None => model_key(name, traffic.lock().unwrap().route_request(name).as_deref()),
+    }
+}
+
+// Run a request on the model it names. Every front end serves predictions
+// through here, so they share one registry and one set of metrics.
+fn predict(
+    request: &PredictRequest,
+    models: &RwLock<HashMap<String, ModelEndpoint>>,
+    traffic: &Mutex<ABTestManager>,
+    metrics: &Mutex<ServerMetrics>,
+) -> Result<PredictResponse, ModelError> {
     let start_time = Instant::now();
     
-    // Update metrics
-    metrics.lock().unwrap().request_count += 1;
-    
-    // Get model endpoint
-    let models_guard = models.read().unwrap();
-    let key = match &request.model_version {
-        Some(version) => format!("{}:{}", request.model_name, version),
-        None => format!("{}:latest", request.model_name),
+    // Get model endpoint. The registry is not held while the model runs, so
+    // versions can be loaded and unloaded meanwhile.
+    let key = resolve(&request.model_name, request.model_version.as_deref(), traffic);
+    let (version, model, cached) = {
+        let models_guard = models.read().unwrap();
+        let Some(endpoint) = models_guard.get(&key) else {
+            let known = models_guard.values().any(|endpoint| endpoint.name == request.model_name);
+            return Err(if known { ModelError::VersionNotFound } else { ModelError::ModelNotFound });
+        };
+        // The cache key, and the response if one is cached
+        let cached = endpoint.config.enable_caching.then(|| {
+            let input_key = cache::cache_key(request);
+            (input_key, endpoint.cache.lock().unwrap().get(input_key, start_time))
+        });
+        (endpoint.version.clone(), Arc::clone(&endpoint.model), cached)
     };
     
-    if let Some(endpoint) = models_guard.get(&key) {
-        // Perform prediction
-        let model = endpoint.model.read().unwrap();
-        match model.predict(&request) {
-            Ok(mut response) => {
-                // Add metadata
-                if let Some(ref mut metadata) = response.metadata {
-                    metadata.inference_time_ms = start_time.elapsed().as_secs_f64() * 1000.0;
-                }
-                
-                // Update metrics
-                metrics.lock().unwrap().success_count += 1;
-                
-                response
-            },
-            Err(e) => {
-                // Update metrics
-                metrics.lock().unwrap().error_count += 1;
-                
-                // Return error response
-                PredictResponse {
-                    id: request.id,
-                    model_name: request.model_name,
-                    model_version: "error".to_string(),
-                    outputs: HashMap::new(),
-                    metadata: None,
-                }
-            }
+    if let Some((_, Some(mut response))) = cached {
+        let latency_ms = start_time.elapsed().as_secs_f64() * 1000.0;
+        if let Some(endpoint) = models.read().unwrap().get(&key) {
+            let mut endpoint_metrics = endpoint.metrics.lock().unwrap();
+            endpoint_metrics.record(latency_ms, true);
+            endpoint_metrics.cache_hits += 1;
         }
-    } else {
-        // Model not found
-        metrics.lock().unwrap().error_count += 1;
+        metrics.lock().unwrap().observe_cache(&request.model_name, &version, true);
         
-        PredictResponse {
-            id: request.id,
-            model_name: request.model_name,
-            model_version: "not_found".to_string(),
-            outputs: HashMap::new(),
-            metadata: None,
+        response.id = request.id.clone();
+        response.model_name = request.model_name.clone();
+        if let Some(ref mut metadata) = response.metadata {
+            metadata.inference_time_ms = latency_ms;
         }
+        return Ok(response);
     }
-}
-
-// Server metrics
-#[derive(Clone)]
-pub struct ServerMetrics {
-    pub request_count: u64,
-    pub success_count: u64,
-    pub error_count: u64,
-    pub avg_latency_ms: f64,
-    pub p50_latency_ms: f64,
-    pub p95_latency_ms: f64,
-    pub p99_latency_ms: f64,
-}
-
-impl ServerMetrics {
-    pub fn new() -> Self {
-        Self {
-            request_count: 0,
-            success_count: 0,
-            error_count: 0,
-            avg_latency_ms: 0.0,
-            p50_latency_ms: 0.0,
-            p95_latency_ms: 0.0,
-            p99_latency_ms: 0.0,
+    
+    // Perform prediction
+    let result = model.read().unwrap().predict(request);
+    let elapsed = start_time.elapsed();
+    let latency_ms = elapsed.as_secs_f64() * 1000.0;
+    let result = result.map(|mut response| {
+        // Add metadata
+        if let Some(ref mut metadata) = response.metadata {
+            metadata.inference_time_ms = latency_ms;
+        }
+        response.model_version = version.clone();
+        response
+    });
+    
+    if let Some(endpoint) = models.read().unwrap().get(&key) {
+        let mut endpoint_metrics = endpoint.metrics.lock().unwrap();
+        endpoint_metrics.record(latency_ms, result.is_ok());
+        if let Some((input_key, _)) = cached {
+            endpoint_metrics.cache_misses += 1;
+            // Nothing from a model since replaced is kept
+            if let (Ok(response), true) = (&result, Arc::ptr_eq(&endpoint.model, &model)) {
+                endpoint.cache.lock().unwrap().insert(input_key, response.clone(), Instant::now());
+            }
         }
     }
+    let batch_size = match &result {
+        Ok(PredictResponse { metadata: Some(metadata), .. }) => metadata.batch_size,
+        _ => request.inputs.values().next().and_then(|tensor| tensor.shape.first().copied()).unwrap_or(1),
+    };
+    let mut metrics = metrics.lock().unwrap();
+    if cached.is_some() {
+        metrics.observe_cache(&request.model_name, &version, false);
+    }
+    metrics.observe_prediction(&request.model_name, &version, elapsed, batch_size, result.is_ok());
+    
+    result
 }
 
 // Endpoint metrics
+#[derive(Clone, Copy, Serialize)]
 pub struct EndpointMetrics {
     pub request_count: u64,
+    pub error_count: u64,
+    pub avg_latency_ms: f64,
     pub cache_hits: u64,
     pub cache_misses: u64,
     pub batch_count: u64,
     pub avg_batch_size: f64,
 }
 
+impl Default for EndpointMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EndpointMetrics {
     pub fn new() -> Self {
         Self {
             request_count: 0,
+            error_count: 0,
+            avg_latency_ms: 0.0,
             cache_hits: 0,
             cache_misses: 0,
             batch_count: 0,
             avg_batch_size: 0.0,
         }
     }
+    
+    fn record(&mut self, latency_ms: f64, success: bool) {
+        self.request_count += 1;
+        if !success {
+            self.error_count += 1;
+        }
+        self.avg_latency_ms += (latency_ms - self.avg_latency_ms) / self.request_count as f64;
+    }
 }
 
 // Server errors
@@ -402,12 +1098,25 @@ pub enum ServerError {
     BindError(String),
     ModelError(String),
     ConfigError(String),
+    IoError(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::BindError(message) => write!(f, "cannot bind: {}", message),
+            ServerError::ModelError(message) => write!(f, "model error: {}", message),
+            ServerError::ConfigError(message) => write!(f, "configuration error: {}", message),
+            ServerError::IoError(message) => write!(f, "I/O error: {}", message),
+        }
+    }
 }
 
 // Thread pool for handling requests
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: std::sync::mpsc::Sender<Job>,
+    // Dropped first when the pool is, so the workers see the channel close
+    sender: Option<std::sync::mpsc::Sender<Job>>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -418,11 +1127,11 @@ impl ThreadPool {
         let receiver = Arc::new(Mutex::new(receiver));
         
         let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        for _ in 0..size {
+            workers.push(Worker::new(Arc::clone(&receiver)));
         }
         
-        Self { workers, sender }
+        Self { workers, sender: Some(sender) }
     }
     
     pub fn execute<F>(&self, f: F)
@@ -430,17 +1139,30 @@ impl ThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
-        self.sender.send(job).unwrap();
+        if let Some(sender) = &self.sender {
+            sender.send(job).unwrap();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    // Let the jobs queued finish, then wait for the workers
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
     }
 }
 
 struct Worker {
-    id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<std::sync::mpsc::Receiver<Job>>>) -> Self {
+    fn new(receiver: Arc<Mutex<std::sync::mpsc::Receiver<Job>>>) -> Self {
         let thread = thread::spawn(move || loop {
             let job = receiver.lock().unwrap().recv();
             
@@ -455,7 +1177,6 @@ impl Worker {
         });
         
         Self {
-            id,
             thread: Some(thread),
         }
     }
@@ -467,11 +1188,12 @@ pub struct ABTestManager {
 }
 
 pub struct Experiment {
-    name: String,
     model_a: String,
     model_b: String,
     traffic_split: f32, // Percentage to model A
     metrics: ExperimentMetrics,
+    // For rollouts: earlier versions of model A, most recent last
+    previous: Vec<String>,
 }
 
 pub struct ExperimentMetrics {
@@ -481,6 +1203,12 @@ pub struct ExperimentMetrics {
     model_b_latency: Vec<f64>,
 }
 
+impl Default for ABTestManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ABTestManager {
     pub fn new() -> Self {
         Self {
@@ -496,7 +1224,6 @@ impl ABTestManager {
         traffic_split: f32,
     ) {
         let experiment = Experiment {
-            name: name.clone(),
             model_a,
             model_b,
             traffic_split,
@@ -506,11 +1233,113 @@ impl ABTestManager {
                 model_a_latency: Vec::new(),
                 model_b_latency: Vec::new(),
             },
+            previous: Vec::new(),
         };
         
         self.experiments.insert(name, experiment);
     }
     
+    pub fn set_traffic_split(&mut self, experiment_name: &str, traffic_split: f32) -> bool {
+        match self.experiments.get_mut(experiment_name) {
+            Some(experiment) => {
+                experiment.traffic_split = traffic_split.clamp(0.0, 1.0);
+                true
+            },
+            None => false,
+        }
+    }
+    
+    // Rollouts are experiments named after a model, with its stable version
+    // as model A and the canary, if any, as model B
+    pub fn rollout(&self, model: &str) -> Option<RolloutStatus> {
+        let experiment = self.experiments.get(model)?;
+        let canary = (experiment.model_b != experiment.model_a).then(|| experiment.model_b.clone());
+        Some(RolloutStatus {
+            stable: experiment.model_a.clone(),
+            canary_traffic: if canary.is_some() { 1.0 - experiment.traffic_split } else { 0.0 },
+            canary,
+            previous: experiment.previous.clone(),
+            stable_requests: experiment.metrics.model_a_requests,
+            canary_requests: experiment.metrics.model_b_requests,
+        })
+    }
+    
+    // Send `share` of a model's traffic to `version`, replacing any canary
+    pub fn start_canary(&mut self, model: &str, version: String, share: f32) {
+        let Some(experiment) = self.experiments.get_mut(model) else {
+            self.create_experiment(model.to_string(), version.clone(), version, 1.0);
+            return;
+        };
+        
+        experiment.traffic_split = if version == experiment.model_a { 1.0 } else { 1.0 - share.clamp(0.0, 1.0) };
+        if version != experiment.model_b {
+            experiment.model_b = version;
+            experiment.metrics.model_b_requests = 0;
+            experiment.metrics.model_b_latency.clear();
+        }
+    }
+    
+    // Make the canary the stable version. Returns the new stable version.
+    pub fn promote(&mut self, model: &str) -> Option<String> {
+        let experiment = self.experiments.get_mut(model)?;
+        if experiment.model_b == experiment.model_a {
+            return None;
+        }
+        
+        let stable = std::mem::replace(&mut experiment.model_a, experiment.model_b.clone());
+        experiment.previous.push(stable);
+        experiment.traffic_split = 1.0;
+        experiment.metrics.model_a_requests = experiment.metrics.model_b_requests;
+        experiment.metrics.model_a_latency = std::mem::take(&mut experiment.metrics.model_b_latency);
+        experiment.metrics.model_b_requests = 0;
+        Some(experiment.model_a.clone())
+    }
+    
+    // Drop the canary, or without one go back to the previous stable
+    // version. Returns the version now stable.
+    pub fn rollback(&mut self, model: &str) -> Option<String> {
+        let experiment = self.experiments.get_mut(model)?;
+        if experiment.model_b == experiment.model_a {
+            let previous = experiment.previous.pop()?;
+            experiment.model_a = previous;
+            experiment.metrics.model_a_requests = 0;
+            experiment.metrics.model_a_latency.clear();
+        }
+        
+        experiment.model_b = experiment.model_a.clone();
+        experiment.traffic_split = 1.0;
+        experiment.metrics.model_b_requests = 0;
+        experiment.metrics.model_b_latency.clear();
+        Some(experiment.model_a.clone())
+    }
+    
+    // Stop routing to a version that has gone, falling back to `fallback`
+    // if it was the only one serving
+    pub fn retire(&mut self, model: &str, version: &str, fallback: Option<String>) {
+        let Some(experiment) = self.experiments.get_mut(model) else {
+            return;
+        };
+        experiment.previous.retain(|previous| previous != version);
+        
+        if experiment.model_b == version && experiment.model_a != version {
+            experiment.model_b = experiment.model_a.clone();
+            experiment.traffic_split = 1.0;
+        } else if experiment.model_a == version {
+            if experiment.model_b != version {
+                self.promote(model);
+                if let Some(experiment) = self.experiments.get_mut(model) {
+                    experiment.previous.retain(|previous| previous != version);
+                }
+            } else if let Some(fallback) = fallback {
+                experiment.model_a = fallback.clone();
+                experiment.model_b = fallback;
+                experiment.traffic_split = 1.0;
+            } else {
+                self.experiments.remove(model);
+            }
+        }
+    }
+    
     pub fn route_request(&mut self, experiment_name: &str) -> Option<String> {
         if let Some(experiment) = self.experiments.get_mut(experiment_name) {
             // Simple random routing based on traffic split
@@ -529,17 +1358,32 @@ impl ABTestManager {
     }
 }
 
+// Where a model's unversioned traffic goes
+#[derive(Clone, Serialize)]
+pub struct RolloutStatus {
+    pub stable: String,
+    pub canary: Option<String>,
+    // Share of traffic sent to the canary
+    pub canary_traffic: f32,
+    // Versions a rollback can return to, most recent last
+    pub previous: Vec<String>,
+    pub stable_requests: u64,
+    pub canary_requests: u64,
+}
+
 // Feature store integration
 pub struct FeatureStore {
     features: HashMap<String, Feature>,
     cache: HashMap<String, CachedFeature>,
 }
 
+type Transformation = Box<dyn Fn(Vec<f32>) -> Vec<f32>>;
+
 pub struct Feature {
-    name: String,
-    dtype: String,
-    source: FeatureSource,
-    transformation: Option<Box<dyn Fn(Vec<f32>) -> Vec<f32>>>,
+    pub name: String,
+    pub dtype: String,
+    pub source: FeatureSource,
+    pub transformation: Option<Transformation>,
 }
 
 pub enum FeatureSource {
@@ -555,6 +1399,12 @@ pub struct CachedFeature {
     ttl: Duration,
 }
 
+impl Default for FeatureStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FeatureStore {
     pub fn new() -> Self {
         Self {
@@ -613,12 +1463,41 @@ impl FeatureStore {
     }
 }
 
-// Random number generation placeholder
+// Random numbers for traffic splitting; not for anything that needs to be
+// unpredictable
 mod rand {
-    pub fn random<T>() -> T
-    where
-        T: Default,
-    {
-        T::default()
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    
+    pub trait Random {
+        fn from_bits(bits: u64) -> Self;
+    }
+    
+    impl Random for f32 {
+        // Uniform in [0, 1)
+        fn from_bits(bits: u64) -> Self {
+            (bits >> 40) as f32 / (1u64 << 24) as f32
+        }
+    }
+    
+    impl Random for u64 {
+        fn from_bits(bits: u64) -> Self {
+            bits
+        }
+    }
+    
+    // SplitMix64 over a shared counter, seeded from the clock on first use
+    pub fn random<T: Random>() -> T {
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        if STATE.load(Ordering::Relaxed) == 0 {
+            let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |now| now.as_nanos() as u64 | 1);
+            let _ = STATE.compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        let mut z = STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        T::from_bits(z ^ (z >> 31))
     }
 }
\ No newline at end of file
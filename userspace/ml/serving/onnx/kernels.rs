@@ -0,0 +1,264 @@
+// Inner loops of the CPU backend, vectorised with AVX2/FMA on x86-64 and
+// NEON on AArch64, with a portable fallback
+
+// y += a * x
+pub fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+    assert_eq!(x.len(), y.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // Safety: the CPU supports the instructions checked for
+            return unsafe { x86::axpy(a, x, y) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Safety: NEON is part of the AArch64 baseline
+        return unsafe { arm::axpy(a, x, y) };
+    }
+
+    #[allow(unreachable_code)]
+    scalar::axpy(a, x, y)
+}
+
+// Sum of x[i] * y[i]
+pub fn dot(x: &[f32], y: &[f32]) -> f32 {
+    assert_eq!(x.len(), y.len());
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+            // Safety: the CPU supports the instructions checked for
+            return unsafe { x86::dot(x, y) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // Safety: NEON is part of the AArch64 baseline
+        return unsafe { arm::dot(x, y) };
+    }
+
+    #[allow(unreachable_code)]
+    scalar::dot(x, y)
+}
+
+// out (m x n) = a (m x k) * b (k x n), all row-major
+pub fn matmul(a: &[f32], b: &[f32], out: &mut [f32], m: usize, k: usize, n: usize) {
+    debug_assert!(a.len() == m * k && b.len() == k * n && out.len() == m * n);
+    out.fill(0.0);
+    if n == 1 {
+        // A matrix-vector product: dot products read both sides in order
+        for (row, out) in a.chunks_exact(k.max(1)).zip(out.iter_mut()) {
+            *out = dot(row, b);
+        }
+        return;
+    }
+    for (row, out) in a.chunks_exact(k.max(1)).zip(out.chunks_exact_mut(n)) {
+        for (&scale, b_row) in row.iter().zip(b.chunks_exact(n)) {
+            if scale != 0.0 {
+                axpy(scale, b_row, out);
+            }
+        }
+    }
+}
+
+// The portable fallback
+mod scalar {
+    pub fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+        for (y, x) in y.iter_mut().zip(x) {
+            *y += a * x;
+        }
+    }
+
+    // Several accumulators so the loop can be vectorised
+    pub fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let mut sums = [0.0f32; 8];
+        let mut x_chunks = x.chunks_exact(8);
+        let mut y_chunks = y.chunks_exact(8);
+        for (x, y) in (&mut x_chunks).zip(&mut y_chunks) {
+            for lane in 0..8 {
+                sums[lane] += x[lane] * y[lane];
+            }
+        }
+        let tail: f32 = x_chunks.remainder().iter().zip(y_chunks.remainder()).map(|(x, y)| x * y).sum();
+        sums.iter().sum::<f32>() + tail
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+        let len = x.len();
+        let scale = _mm256_set1_ps(a);
+        let mut i = 0;
+        while i + 8 <= len {
+            let result = _mm256_fmadd_ps(scale, _mm256_loadu_ps(x.as_ptr().add(i)), _mm256_loadu_ps(y.as_ptr().add(i)));
+            _mm256_storeu_ps(y.as_mut_ptr().add(i), result);
+            i += 8;
+        }
+        while i < len {
+            *y.get_unchecked_mut(i) += a * *x.get_unchecked(i);
+            i += 1;
+        }
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let len = x.len();
+        let mut low = _mm256_setzero_ps();
+        let mut high = _mm256_setzero_ps();
+        let mut i = 0;
+        while i + 16 <= len {
+            low = _mm256_fmadd_ps(_mm256_loadu_ps(x.as_ptr().add(i)), _mm256_loadu_ps(y.as_ptr().add(i)), low);
+            high = _mm256_fmadd_ps(_mm256_loadu_ps(x.as_ptr().add(i + 8)), _mm256_loadu_ps(y.as_ptr().add(i + 8)), high);
+            i += 16;
+        }
+        if i + 8 <= len {
+            low = _mm256_fmadd_ps(_mm256_loadu_ps(x.as_ptr().add(i)), _mm256_loadu_ps(y.as_ptr().add(i)), low);
+            i += 8;
+        }
+
+        let mut lanes = [0.0f32; 8];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), _mm256_add_ps(low, high));
+        let mut sum: f32 = lanes.iter().sum();
+        while i < len {
+            sum += *x.get_unchecked(i) * *y.get_unchecked(i);
+            i += 1;
+        }
+        sum
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+
+    pub unsafe fn axpy(a: f32, x: &[f32], y: &mut [f32]) {
+        let len = x.len();
+        let scale = vdupq_n_f32(a);
+        let mut i = 0;
+        while i + 4 <= len {
+            let result = vfmaq_f32(vld1q_f32(y.as_ptr().add(i)), scale, vld1q_f32(x.as_ptr().add(i)));
+            vst1q_f32(y.as_mut_ptr().add(i), result);
+            i += 4;
+        }
+        while i < len {
+            *y.get_unchecked_mut(i) += a * *x.get_unchecked(i);
+            i += 1;
+        }
+    }
+
+    pub unsafe fn dot(x: &[f32], y: &[f32]) -> f32 {
+        let len = x.len();
+        let mut low = vdupq_n_f32(0.0);
+        let mut high = vdupq_n_f32(0.0);
+        let mut i = 0;
+        while i + 8 <= len {
+            low = vfmaq_f32(low, vld1q_f32(x.as_ptr().add(i)), vld1q_f32(y.as_ptr().add(i)));
+            high = vfmaq_f32(high, vld1q_f32(x.as_ptr().add(i + 4)), vld1q_f32(y.as_ptr().add(i + 4)));
+            i += 8;
+        }
+        let mut sum = vaddvq_f32(vaddq_f32(low, high));
+        while i < len {
+            sum += *x.get_unchecked(i) * *y.get_unchecked(i);
+            i += 1;
+        }
+        sum
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values that are not all representable sums, so a wrong lane or a
+    // dropped tail element shows
+    fn values(len: usize, seed: u32) -> Vec<f32> {
+        (0..len as u32).map(|i| ((i * 7919 + seed * 104729) % 1000) as f32 / 100.0 - 5.0).collect()
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() <= 1e-4 * expected.abs().max(1.0), "{} vs {}", actual, expected);
+    }
+
+    // Every length up to a few vectors, to cover each tail length
+    const LENGTHS: std::ops::Range<usize> = 0..50;
+
+    #[test]
+    fn dot_matches_a_plain_sum() {
+        for len in LENGTHS {
+            let (x, y) = (values(len, 1), values(len, 2));
+            let expected: f32 = x.iter().zip(&y).map(|(x, y)| x * y).sum();
+            assert_close(dot(&x, &y), expected);
+            assert_close(scalar::dot(&x, &y), expected);
+        }
+    }
+
+    #[test]
+    fn axpy_matches_a_plain_loop() {
+        for len in LENGTHS {
+            let x = values(len, 3);
+            let mut y = values(len, 4);
+            let expected: Vec<f32> = x.iter().zip(&y).map(|(x, y)| y + 1.5 * x).collect();
+            axpy(1.5, &x, &mut y);
+            for (actual, expected) in y.iter().zip(&expected) {
+                assert_close(*actual, *expected);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_agrees_with_the_fallback() {
+        if !is_x86_feature_detected!("avx2") || !is_x86_feature_detected!("fma") {
+            return;
+        }
+        for len in LENGTHS {
+            let (x, y) = (values(len, 5), values(len, 6));
+            // Safety: the CPU supports the instructions checked for
+            assert_close(unsafe { x86::dot(&x, &y) }, scalar::dot(&x, &y));
+
+            let (mut simd, mut portable) = (y.clone(), y);
+            unsafe { x86::axpy(-0.25, &x, &mut simd) };
+            scalar::axpy(-0.25, &x, &mut portable);
+            for (simd, portable) in simd.iter().zip(&portable) {
+                assert_close(*simd, *portable);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn neon_agrees_with_the_fallback() {
+        for len in LENGTHS {
+            let (x, y) = (values(len, 5), values(len, 6));
+            // Safety: NEON is part of the AArch64 baseline
+            assert_close(unsafe { arm::dot(&x, &y) }, scalar::dot(&x, &y));
+
+            let (mut simd, mut portable) = (y.clone(), y);
+            unsafe { arm::axpy(-0.25, &x, &mut simd) };
+            scalar::axpy(-0.25, &x, &mut portable);
+            for (simd, portable) in simd.iter().zip(&portable) {
+                assert_close(*simd, *portable);
+            }
+        }
+    }
+
+    #[test]
+    fn matmul_matches_the_definition() {
+        for (m, k, n) in [(1, 1, 1), (3, 5, 1), (2, 9, 17), (4, 0, 3), (5, 16, 8)] {
+            let (a, b) = (values(m * k, 7), values(k * n, 8));
+            let mut out = vec![f32::NAN; m * n];
+            matmul(&a, &b, &mut out, m, k, n);
+            for i in 0..m {
+                for j in 0..n {
+                    let expected: f32 = (0..k).map(|p| a[i * k + p] * b[p * n + j]).sum();
+                    assert_close(out[i * n + j], expected);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// ONNX models, loaded from a file and run on the CPU
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    time::Instant,
+};
+
+use prost::Message;
+
+use super::{
+    Model, ModelError, ModelInfo, PredictRequest, PredictResponse, ResponseMetadata, ServerError, TensorData,
+    TensorSpec, TensorValues,
+};
+
+mod kernels;
+mod ops;
+mod proto;
+
+use self::ops::{Node, Tensor};
+use self::proto::{DimensionValue, ValueInfoProto};
+
+// A model graph checked at load time, so every request runs operators this
+// backend supports in an order where each input is already computed
+pub struct OnnxModel {
+    info: ModelInfo,
+    opset: i64,
+    nodes: Vec<Node>,
+    initializers: HashMap<String, Tensor>,
+    // Values no later node reads, freed after each node runs
+    released: Vec<Vec<String>>,
+}
+
+impl OnnxModel {
+    // Load a model file. Tensors stored as external data are read from the
+    // model's directory.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ServerError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .map_err(|e| ServerError::ModelError(format!("cannot read {}: {}", path.display(), e)))?;
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        Self::from_bytes(&bytes, &name, path.parent())
+            .map_err(|e| ServerError::ModelError(format!("{}: {}", path.display(), e)))
+    }
+    
+    // Load a model held in memory, named `name` if its graph has no name
+    pub fn from_bytes(bytes: &[u8], name: &str, dir: Option<&Path>) -> Result<Self, String> {
+        let model = proto::ModelProto::decode(bytes).map_err(|e| format!("not an ONNX model: {}", e))?;
+        let graph = model.graph.as_ref().ok_or("the model has no graph")?;
+        let opset = model.opset_import.iter()
+            .find(|opset| opset.domain.is_empty() || opset.domain == "ai.onnx")
+            .map(|opset| opset.version)
+            .ok_or("the model does not import the ai.onnx operator set")?;
+        
+        let mut initializers = HashMap::new();
+        for tensor in &graph.initializer {
+            initializers.insert(tensor.name.clone(), Tensor::from_proto(tensor, dir)?);
+        }
+        
+        // Older exporters also list initializers as graph inputs
+        let inputs: Vec<&ValueInfoProto> = graph.input.iter().filter(|input| !initializers.contains_key(&input.name)).collect();
+        let mut known: HashSet<&str> = initializers.keys().map(String::as_str).collect();
+        known.extend(inputs.iter().map(|input| input.name.as_str()));
+        
+        let mut nodes = Vec::with_capacity(graph.node.len());
+        for node in &graph.node {
+            if !node.domain.is_empty() && node.domain != "ai.onnx" {
+                return Err(format!("operator {}.{} is not supported", node.domain, node.op_type));
+            }
+            if !ops::SUPPORTED.contains(&node.op_type.as_str()) {
+                return Err(format!("operator {} is not supported", node.op_type));
+            }
+            if let Some(input) = node.input.iter().find(|input| !input.is_empty() && !known.contains(input.as_str())) {
+                return Err(format!("node '{}' reads '{}' before it is computed", node.name, input));
+            }
+            known.extend(node.output.iter().map(String::as_str));
+            nodes.push(Node::from_proto(node));
+        }
+        if let Some(output) = graph.output.iter().find(|output| !known.contains(output.name.as_str())) {
+            return Err(format!("graph output '{}' is never computed", output.name));
+        }
+        
+        // Free each computed value after the last node that reads it
+        let outputs: HashSet<&str> = graph.output.iter().map(|output| output.name.as_str()).collect();
+        let mut last_use = HashMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for input in &node.inputs {
+                last_use.insert(input.as_str(), index);
+            }
+        }
+        let mut released = vec![Vec::new(); nodes.len()];
+        for (name, index) in last_use {
+            if !name.is_empty() && !outputs.contains(name) && !initializers.contains_key(name) {
+                released[index].push(name.to_string());
+            }
+        }
+        
+        let mut metadata: HashMap<String, String> = model.metadata_props.iter()
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect();
+        metadata.insert("producer".to_string(), format!("{} {}", model.producer_name, model.producer_version).trim().to_string());
+        metadata.insert("ir_version".to_string(), model.ir_version.to_string());
+        metadata.insert("opset".to_string(), opset.to_string());
+        if !model.domain.is_empty() {
+            metadata.insert("domain".to_string(), model.domain.clone());
+        }
+        if !model.doc_string.is_empty() {
+            metadata.insert("description".to_string(), model.doc_string.clone());
+        }
+        
+        let info = ModelInfo {
+            name: if graph.name.is_empty() { name.to_string() } else { graph.name.clone() },
+            version: model.model_version.to_string(),
+            framework: "onnx".to_string(),
+            inputs: inputs.into_iter().map(tensor_spec).collect(),
+            outputs: graph.output.iter().map(tensor_spec).collect(),
+            metadata,
+        };
+        
+        Ok(Self { info, opset, nodes, initializers, released })
+    }
+    
+    // Run the graph on named inputs, returning the graph outputs
+    fn run(&self, feeds: HashMap<&str, Tensor>) -> Result<HashMap<String, Tensor>, ModelError> {
+        let mut values: HashMap<&str, Cow<Tensor>> = self.initializers.iter()
+            .map(|(name, tensor)| (name.as_str(), Cow::Borrowed(tensor)))
+            .collect();
+        values.extend(feeds.into_iter().map(|(name, tensor)| (name, Cow::Owned(tensor))));
+        
+        for (node, released) in self.nodes.iter().zip(&self.released) {
+            let inputs: Vec<Option<&Tensor>> = node.inputs.iter()
+                .map(|name| values.get(name.as_str()).map(|value| value.as_ref()))
+                .collect();
+            let outputs = ops::run(node, &inputs, self.opset)
+                .map_err(|e| ModelError::InferenceError(format!("{} ({}): {}", node.name, node.op, e)))?;
+            for (name, tensor) in node.outputs.iter().zip(outputs) {
+                if !name.is_empty() {
+                    values.insert(name, Cow::Owned(tensor));
+                }
+            }
+            for name in released {
+                values.remove(name.as_str());
+            }
+        }
+        
+        self.info.outputs.iter()
+            .map(|spec| {
+                let tensor = values.remove(spec.name.as_str())
+                    .ok_or_else(|| ModelError::InferenceError(format!("output '{}' was not computed", spec.name)))?;
+                Ok((spec.name.clone(), tensor.into_owned()))
+            })
+            .collect()
+    }
+}
+
+impl Model for OnnxModel {
+    fn predict(&self, input: &PredictRequest) -> Result<PredictResponse, ModelError> {
+        let start = Instant::now();
+        
+        if let Some(name) = input.inputs.keys().find(|name| !self.info.inputs.iter().any(|spec| &spec.name == *name)) {
+            return Err(ModelError::InvalidInput(format!("the model has no input '{}'", name)));
+        }
+        let mut feeds = HashMap::new();
+        for spec in &self.info.inputs {
+            let data = input.inputs.get(&spec.name)
+                .ok_or_else(|| ModelError::InvalidInput(format!("missing input '{}'", spec.name)))?;
+            feeds.insert(spec.name.as_str(), to_tensor(spec, data)?);
+        }
+        let batch_size = feeds.values().next().and_then(|tensor| tensor.shape.first().copied()).unwrap_or(1);
+        
+        let outputs = self.run(feeds)?
+            .into_iter()
+            .map(|(name, tensor)| {
+                let data = TensorData {
+                    shape: tensor.shape,
+                    dtype: "float32".to_string(),
+                    data: TensorValues::Float32(tensor.data),
+                };
+                (name, data)
+            })
+            .collect();
+        
+        Ok(PredictResponse {
+            id: input.id.clone(),
+            model_name: input.model_name.clone(),
+            model_version: self.info.version.clone(),
+            outputs,
+            metadata: Some(ResponseMetadata {
+                inference_time_ms: start.elapsed().as_secs_f64() * 1000.0,
+                preprocessing_time_ms: 0.0,
+                postprocessing_time_ms: 0.0,
+                model_version: self.info.version.clone(),
+                batch_size,
+            }),
+        })
+    }
+    
+    fn batch_predict(&self, inputs: &[PredictRequest]) -> Result<Vec<PredictResponse>, ModelError> {
+        inputs.iter().map(|input| self.predict(input)).collect()
+    }
+    
+    fn get_info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+}
+
+// Convert request data for an input, checking it against the declared shape
+fn to_tensor(spec: &TensorSpec, data: &TensorData) -> Result<Tensor, ModelError> {
+    let values = match &data.data {
+        TensorValues::Float32(values) => values.clone(),
+        TensorValues::Float64(values) => values.iter().map(|&value| value as f32).collect(),
+        TensorValues::Int32(values) => values.iter().map(|&value| value as f32).collect(),
+        TensorValues::Int64(values) => values.iter().map(|&value| value as f32).collect(),
+        TensorValues::String(_) | TensorValues::Bytes(_) => {
+            return Err(ModelError::InvalidInput(format!("input '{}' must be numeric", spec.name)));
+        }
+    };
+    
+    let fits = spec.shape.is_empty()
+        || (spec.shape.len() == data.shape.len()
+            && spec.shape.iter().zip(&data.shape).all(|(&declared, &actual)| declared < 0 || declared as usize == actual));
+    if !fits {
+        return Err(ModelError::InvalidInput(format!(
+            "input '{}' has shape {:?}, expected {:?}",
+            spec.name, data.shape, spec.shape
+        )));
+    }
+    Tensor::new(data.shape.clone(), values).map_err(|e| ModelError::InvalidInput(format!("input '{}': {}", spec.name, e)))
+}
+
+fn tensor_spec(info: &ValueInfoProto) -> TensorSpec {
+    let tensor = info.r#type.as_ref().and_then(|r#type| r#type.tensor_type.as_ref());
+    let shape = tensor
+        .and_then(|tensor| tensor.shape.as_ref())
+        .map(|shape| {
+            shape.dim.iter()
+                .map(|dimension| match dimension.value {
+                    Some(DimensionValue::DimValue(size)) if size >= 0 => size as isize,
+                    _ => -1,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let dtype = match tensor.map_or(proto::FLOAT, |tensor| tensor.elem_type) {
+        proto::FLOAT => "float32",
+        proto::DOUBLE => "float64",
+        proto::INT8 => "int8",
+        proto::INT16 => "int16",
+        proto::INT32 => "int32",
+        proto::INT64 => "int64",
+        proto::UINT8 => "uint8",
+        proto::UINT16 => "uint16",
+        proto::UINT32 => "uint32",
+        proto::UINT64 => "uint64",
+        proto::BOOL => "bool",
+        _ => "unknown",
+    };
+    TensorSpec { name: info.name.clone(), shape, dtype: dtype.to_string() }
+}
\ No newline at end of file
@@ -0,0 +1,1095 @@
+// Tensors and the ONNX operators the CPU backend runs
+use std::{collections::HashMap, fs, path::Path};
+
+use super::kernels;
+use super::proto::{self, AttributeProto, NodeProto, TensorProto};
+
+// Operators of the default domain that can be run
+pub const SUPPORTED: &[&str] = &[
+    "Add", "AveragePool", "BatchNormalization", "Clip", "Concat", "Constant", "Conv", "Div", "Dropout", "Flatten",
+    "Gather", "Gemm", "GlobalAveragePool", "GlobalMaxPool", "Identity", "LeakyRelu", "LogSoftmax", "MatMul", "MaxPool",
+    "Mul", "Relu", "Reshape", "Shape", "Sigmoid", "Softmax", "Squeeze", "Sub", "Tanh", "Transpose", "Unsqueeze",
+];
+
+// A dense row-major tensor. Every element type is held as f32, which is
+// exact for the integer shapes and indices models compute with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl Tensor {
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> Result<Self, String> {
+        let count: usize = shape.iter().product();
+        if count != data.len() {
+            return Err(format!("{} values do not fill shape {:?}", data.len(), shape));
+        }
+        Ok(Self { shape, data })
+    }
+    
+    // Decode an initializer or constant. `dir` is where external data files
+    // are looked for.
+    pub fn from_proto(tensor: &TensorProto, dir: Option<&Path>) -> Result<Self, String> {
+        let shape = tensor.dims.iter()
+            .map(|&dimension| usize::try_from(dimension).map_err(|_| format!("bad dimension {}", dimension)))
+            .collect::<Result<Vec<usize>, String>>()?;
+        
+        let external;
+        let raw = if tensor.data_location == 1 {
+            external = read_external(tensor, dir)?;
+            Some(external.as_slice())
+        } else if !tensor.raw_data.is_empty() {
+            Some(tensor.raw_data.as_slice())
+        } else {
+            None
+        };
+        
+        let data = match (tensor.data_type, raw) {
+            (proto::FLOAT, Some(raw)) => from_le(raw, f32::from_le_bytes),
+            (proto::DOUBLE, Some(raw)) => from_le(raw, |bytes| f64::from_le_bytes(bytes) as f32),
+            (proto::INT64, Some(raw)) => from_le(raw, |bytes| i64::from_le_bytes(bytes) as f32),
+            (proto::INT32, Some(raw)) => from_le(raw, |bytes| i32::from_le_bytes(bytes) as f32),
+            (proto::UINT32, Some(raw)) => from_le(raw, |bytes| u32::from_le_bytes(bytes) as f32),
+            (proto::UINT64, Some(raw)) => from_le(raw, |bytes| u64::from_le_bytes(bytes) as f32),
+            (proto::INT16, Some(raw)) => from_le(raw, |bytes| i16::from_le_bytes(bytes) as f32),
+            (proto::UINT16, Some(raw)) => from_le(raw, |bytes| u16::from_le_bytes(bytes) as f32),
+            (proto::INT8, Some(raw)) => raw.iter().map(|&byte| byte as i8 as f32).collect(),
+            (proto::UINT8 | proto::BOOL, Some(raw)) => raw.iter().map(|&byte| byte as f32).collect(),
+            (proto::FLOAT, None) => tensor.float_data.clone(),
+            (proto::DOUBLE, None) => tensor.double_data.iter().map(|&value| value as f32).collect(),
+            (proto::INT64, None) => tensor.int64_data.iter().map(|&value| value as f32).collect(),
+            (proto::INT32 | proto::INT16 | proto::INT8 | proto::UINT16 | proto::UINT8 | proto::BOOL, None) => {
+                tensor.int32_data.iter().map(|&value| value as f32).collect()
+            }
+            (proto::UINT32 | proto::UINT64, None) => tensor.uint64_data.iter().map(|&value| value as f32).collect(),
+            (data_type, _) => return Err(format!("tensor '{}' has unsupported data type {}", tensor.name, data_type)),
+        };
+        Tensor::new(shape, data).map_err(|e| format!("tensor '{}': {}", tensor.name, e))
+    }
+    
+    fn scalar(value: f32) -> Self {
+        Self { shape: Vec::new(), data: vec![value] }
+    }
+    
+    fn reshaped(&self, shape: Vec<usize>) -> Result<Self, String> {
+        Tensor::new(shape, self.data.clone())
+    }
+    
+    // The values as integers, for shapes, axes and indices
+    fn ints(&self) -> Vec<i64> {
+        self.data.iter().map(|&value| value as i64).collect()
+    }
+}
+
+fn from_le<const N: usize>(raw: &[u8], convert: impl Fn([u8; N]) -> f32) -> Vec<f32> {
+    raw.chunks_exact(N).map(|chunk| convert(chunk.try_into().unwrap())).collect()
+}
+
+fn read_external(tensor: &TensorProto, dir: Option<&Path>) -> Result<Vec<u8>, String> {
+    let entry = |key: &str| tensor.external_data.iter().find(|entry| entry.key == key).map(|entry| entry.value.as_str());
+    let location = entry("location").ok_or_else(|| format!("tensor '{}' has no external data location", tensor.name))?;
+    let dir = dir.ok_or_else(|| format!("tensor '{}' cannot use external data here", tensor.name))?;
+    
+    // Data files must sit beside the model
+    let location = Path::new(location);
+    if location.components().any(|component| !matches!(component, std::path::Component::Normal(_))) {
+        return Err(format!("tensor '{}' has an unsafe external data location", tensor.name));
+    }
+    let bytes = fs::read(dir.join(location)).map_err(|e| format!("cannot read {}: {}", location.display(), e))?;
+    
+    let number = |key: &str| entry(key).map(|value| value.parse::<usize>().map_err(|_| format!("bad external data {}", key)));
+    let offset = number("offset").transpose()?.unwrap_or(0);
+    let length = number("length").transpose()?.unwrap_or(bytes.len().saturating_sub(offset));
+    bytes.get(offset..offset.saturating_add(length))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| format!("external data of tensor '{}' is out of range", tensor.name))
+}
+
+// A node ready to run
+pub struct Node {
+    pub name: String,
+    pub op: String,
+    // Empty names stand for optional inputs left out
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    attributes: HashMap<String, AttributeProto>,
+}
+
+impl Node {
+    pub fn from_proto(node: &NodeProto) -> Self {
+        Self {
+            name: if node.name.is_empty() { node.output.first().cloned().unwrap_or_default() } else { node.name.clone() },
+            op: node.op_type.clone(),
+            inputs: node.input.clone(),
+            outputs: node.output.clone(),
+            attributes: node.attribute.iter().map(|attribute| (attribute.name.clone(), attribute.clone())).collect(),
+        }
+    }
+    
+    fn int(&self, name: &str, default: i64) -> i64 {
+        self.attributes.get(name).map_or(default, |attribute| attribute.i)
+    }
+    
+    fn float(&self, name: &str, default: f32) -> f32 {
+        self.attributes.get(name).map_or(default, |attribute| attribute.f)
+    }
+    
+    fn ints(&self, name: &str) -> Option<Vec<i64>> {
+        self.attributes.get(name).map(|attribute| attribute.ints.clone())
+    }
+    
+    fn string(&self, name: &str) -> Option<String> {
+        self.attributes.get(name).map(|attribute| String::from_utf8_lossy(&attribute.s).into_owned())
+    }
+}
+
+// Run a node on its inputs, in order, with None for optional inputs left
+// out. `opset` is the model's ai.onnx operator set version.
+pub fn run(node: &Node, inputs: &[Option<&Tensor>], opset: i64) -> Result<Vec<Tensor>, String> {
+    let input = |index: usize| -> Result<&Tensor, String> {
+        inputs.get(index).copied().flatten().ok_or_else(|| format!("missing input {}", index))
+    };
+    let optional = |index: usize| inputs.get(index).copied().flatten();
+    
+    let output = match node.op.as_str() {
+        "Add" => broadcast(input(0)?, input(1)?, |a, b| a + b)?,
+        "Sub" => broadcast(input(0)?, input(1)?, |a, b| a - b)?,
+        "Mul" => broadcast(input(0)?, input(1)?, |a, b| a * b)?,
+        "Div" => broadcast(input(0)?, input(1)?, |a, b| a / b)?,
+        "Relu" => map(input(0)?, |x| x.max(0.0)),
+        "LeakyRelu" => {
+            let alpha = node.float("alpha", 0.01);
+            map(input(0)?, |x| if x < 0.0 { alpha * x } else { x })
+        }
+        "Sigmoid" => map(input(0)?, |x| 1.0 / (1.0 + (-x).exp())),
+        "Tanh" => map(input(0)?, f32::tanh),
+        "Clip" => {
+            let (min, max) = if opset < 11 {
+                (node.float("min", f32::NEG_INFINITY), node.float("max", f32::INFINITY))
+            } else {
+                (
+                    optional(1).map_or(f32::NEG_INFINITY, |min| min.data[0]),
+                    optional(2).map_or(f32::INFINITY, |max| max.data[0]),
+                )
+            };
+            map(input(0)?, |x| x.max(min).min(max))
+        }
+        "Identity" | "Dropout" => input(0)?.clone(),
+        "Softmax" | "LogSoftmax" => softmax(node, input(0)?, opset)?,
+        "MatMul" => matmul(input(0)?, input(1)?)?,
+        "Gemm" => gemm(node, input(0)?, input(1)?, optional(2))?,
+        "Conv" => conv(node, input(0)?, input(1)?, optional(2))?,
+        "MaxPool" | "AveragePool" => {
+            if node.outputs.get(1).is_some_and(|name| !name.is_empty()) {
+                return Err("the Indices output of MaxPool is not supported".to_string());
+            }
+            pool(node, input(0)?)?
+        }
+        "GlobalAveragePool" | "GlobalMaxPool" => global_pool(node, input(0)?)?,
+        "BatchNormalization" => batch_norm(node, input(0)?, input(1)?, input(2)?, input(3)?, input(4)?)?,
+        "Flatten" => {
+            let data = input(0)?;
+            // The axis may be the rank itself, but -1 is the last dimension
+            let axis = match node.int("axis", 1) {
+                index if index < 0 => axis(index, data.shape.len())?,
+                index => axis(index, data.shape.len() + 1)?,
+            };
+            let outer = data.shape[..axis].iter().product();
+            let inner = data.shape[axis..].iter().product();
+            data.reshaped(vec![outer, inner])?
+        }
+        "Reshape" => {
+            let shape = if opset < 5 { node.ints("shape").unwrap_or_default() } else { input(1)?.ints() };
+            reshape(input(0)?, &shape, node.int("allowzero", 0) != 0)?
+        }
+        "Transpose" => transpose(input(0)?, node.ints("perm"))?,
+        "Concat" => {
+            let parts = inputs.iter().copied().flatten().collect::<Vec<&Tensor>>();
+            concat(&parts, node.int("axis", 0))?
+        }
+        "Squeeze" | "Unsqueeze" => {
+            let axes = if opset < 13 { node.ints("axes") } else { optional(1).map(Tensor::ints) };
+            if node.op == "Squeeze" {
+                squeeze(input(0)?, axes)?
+            } else {
+                unsqueeze(input(0)?, &axes.ok_or("Unsqueeze needs axes")?)?
+            }
+        }
+        "Constant" => constant(node)?,
+        "Shape" => {
+            let rank = input(0)?.shape.len() as i64;
+            let clamp = |index: i64| (if index < 0 { index + rank } else { index }).clamp(0, rank) as usize;
+            let start = clamp(node.int("start", 0));
+            let end = clamp(node.int("end", rank));
+            let dims: Vec<f32> = input(0)?.shape[start..end.max(start)].iter().map(|&dimension| dimension as f32).collect();
+            Tensor::new(vec![dims.len()], dims)?
+        }
+        "Gather" => gather(input(0)?, input(1)?, node.int("axis", 0))?,
+        op => return Err(format!("operator {} is not supported", op)),
+    };
+    Ok(vec![output])
+}
+
+fn map(tensor: &Tensor, f: impl Fn(f32) -> f32) -> Tensor {
+    Tensor {
+        shape: tensor.shape.clone(),
+        data: tensor.data.iter().map(|&x| f(x)).collect(),
+    }
+}
+
+// Normalise a possibly negative axis against a rank
+fn axis(axis: i64, rank: usize) -> Result<usize, String> {
+    let resolved = if axis < 0 { axis + rank as i64 } else { axis };
+    if resolved < 0 || resolved >= rank.max(1) as i64 {
+        return Err(format!("axis {} is out of range for rank {}", axis, rank));
+    }
+    Ok(resolved as usize)
+}
+
+fn broadcast_shape(a: &[usize], b: &[usize]) -> Result<Vec<usize>, String> {
+    let rank = a.len().max(b.len());
+    let dimension = |shape: &[usize], i: usize| if i < rank - shape.len() { 1 } else { shape[i - (rank - shape.len())] };
+    (0..rank)
+        .map(|i| match (dimension(a, i), dimension(b, i)) {
+            (x, y) if x == y || y == 1 => Ok(x),
+            (1, y) => Ok(y),
+            _ => Err(format!("shapes {:?} and {:?} cannot be broadcast together", a, b)),
+        })
+        .collect()
+}
+
+// Element strides of `shape` read as `target`, zero along broadcast axes
+fn broadcast_strides(shape: &[usize], target: &[usize]) -> Vec<usize> {
+    let mut strides = vec![0; target.len()];
+    let offset = target.len() - shape.len();
+    let mut stride = 1;
+    for i in (0..shape.len()).rev() {
+        if shape[i] != 1 {
+            strides[offset + i] = stride;
+        }
+        stride *= shape[i];
+    }
+    strides
+}
+
+// Visit every element of `shape` in order, with the matching offsets into
+// tensors laid out with the given strides
+fn for_each_offset(shape: &[usize], a_strides: &[usize], b_strides: &[usize], mut f: impl FnMut(usize, usize)) {
+    let total: usize = shape.iter().product();
+    let mut index = vec![0; shape.len()];
+    let (mut a, mut b) = (0, 0);
+    for _ in 0..total {
+        f(a, b);
+        for d in (0..shape.len()).rev() {
+            index[d] += 1;
+            a += a_strides[d];
+            b += b_strides[d];
+            if index[d] < shape[d] {
+                break;
+            }
+            a -= a_strides[d] * shape[d];
+            b -= b_strides[d] * shape[d];
+            index[d] = 0;
+        }
+    }
+}
+
+fn broadcast(a: &Tensor, b: &Tensor, f: impl Fn(f32, f32) -> f32) -> Result<Tensor, String> {
+    if a.shape == b.shape {
+        let data = a.data.iter().zip(&b.data).map(|(&x, &y)| f(x, y)).collect();
+        return Ok(Tensor { shape: a.shape.clone(), data });
+    }
+    
+    let shape = broadcast_shape(&a.shape, &b.shape)?;
+    if b.data.len() == 1 && shape == a.shape {
+        let y = b.data[0];
+        return Ok(map(a, |x| f(x, y)));
+    }
+    let mut data = Vec::with_capacity(shape.iter().product());
+    let (a_strides, b_strides) = (broadcast_strides(&a.shape, &shape), broadcast_strides(&b.shape, &shape));
+    for_each_offset(&shape, &a_strides, &b_strides, |i, j| data.push(f(a.data[i], b.data[j])));
+    Ok(Tensor { shape, data })
+}
+
+// Matrix product with NumPy semantics: 1-D operands are promoted to
+// matrices and leading batch dimensions broadcast
+fn matmul(a: &Tensor, b: &Tensor) -> Result<Tensor, String> {
+    if a.shape.is_empty() || b.shape.is_empty() {
+        return Err("MatMul operands must have at least one dimension".to_string());
+    }
+    let a_shape = if a.shape.len() == 1 { vec![1, a.shape[0]] } else { a.shape.clone() };
+    let b_shape = if b.shape.len() == 1 { vec![b.shape[0], 1] } else { b.shape.clone() };
+    let (m, k) = (a_shape[a_shape.len() - 2], a_shape[a_shape.len() - 1]);
+    let (k2, n) = (b_shape[b_shape.len() - 2], b_shape[b_shape.len() - 1]);
+    if k != k2 {
+        return Err(format!("cannot multiply shapes {:?} and {:?}", a.shape, b.shape));
+    }
+    
+    let a_batch = &a_shape[..a_shape.len() - 2];
+    let b_batch = &b_shape[..b_shape.len() - 2];
+    let batch = broadcast_shape(a_batch, b_batch)?;
+    let count: usize = batch.iter().product();
+    let mut data = vec![0.0; count * m * n];
+    
+    let mut products = data.chunks_exact_mut((m * n).max(1));
+    let (a_strides, b_strides) = (broadcast_strides(a_batch, &batch), broadcast_strides(b_batch, &batch));
+    for_each_offset(&batch, &a_strides, &b_strides, |i, j| {
+        let out = products.next().unwrap();
+        kernels::matmul(&a.data[i * m * k..(i + 1) * m * k], &b.data[j * k * n..(j + 1) * k * n], out, m, k, n);
+    });
+    
+    let mut shape = batch;
+    if a.shape.len() > 1 {
+        shape.push(m);
+    }
+    if b.shape.len() > 1 {
+        shape.push(n);
+    }
+    Tensor::new(shape, data)
+}
+
+fn transposed_2d(tensor: &Tensor) -> Result<Tensor, String> {
+    transpose(tensor, Some(vec![1, 0]))
+}
+
+fn gemm(node: &Node, a: &Tensor, b: &Tensor, c: Option<&Tensor>) -> Result<Tensor, String> {
+    if a.shape.len() != 2 || b.shape.len() != 2 {
+        return Err("Gemm operands must be matrices".to_string());
+    }
+    let a = if node.int("transA", 0) != 0 { transposed_2d(a)? } else { a.clone() };
+    let b = if node.int("transB", 0) != 0 { transposed_2d(b)? } else { b.clone() };
+    let alpha = node.float("alpha", 1.0);
+    let beta = node.float("beta", 1.0);
+    
+    let mut product = matmul(&a, &b)?;
+    if alpha != 1.0 {
+        product.data.iter_mut().for_each(|value| *value *= alpha);
+    }
+    match c {
+        Some(c) if beta != 0.0 => {
+            let sum = broadcast(&product, c, |x, y| x + beta * y)?;
+            if sum.shape != product.shape {
+                return Err(format!("Gemm bias of shape {:?} does not fit {:?}", c.shape, product.shape));
+            }
+            Ok(sum)
+        }
+        _ => Ok(product),
+    }
+}
+
+// Geometry of a 2-D sliding window. Inputs with one spatial dimension are
+// handled as height 1.
+struct Window {
+    kernel: [usize; 2],
+    strides: [usize; 2],
+    dilations: [usize; 2],
+    // Top and left padding
+    pads: [usize; 2],
+    output: [usize; 2],
+}
+
+impl Window {
+    fn new(node: &Node, input: [usize; 2], kernel: [usize; 2], spatial: usize, ceil_mode: bool) -> Result<Self, String> {
+        let pair = |name: &str, default: usize| -> Result<[usize; 2], String> {
+            match node.ints(name) {
+                None => Ok([default; 2]),
+                Some(values) if values.len() == spatial && values.iter().all(|&value| value > 0) => {
+                    Ok(if spatial == 1 { [1, values[0] as usize] } else { [values[0] as usize, values[1] as usize] })
+                }
+                Some(values) => Err(format!("bad {} {:?}", name, values)),
+            }
+        };
+        let strides = pair("strides", 1)?;
+        let dilations = pair("dilations", 1)?;
+        let span = |d: usize| dilations[d] * (kernel[d] - 1) + 1;
+        
+        let auto_pad = node.string("auto_pad").unwrap_or_default();
+        let mut pads = [0; 2];
+        let mut output = [0; 2];
+        for d in 0..2 {
+            let (begin, end) = match auto_pad.as_str() {
+                "" | "NOTSET" => match node.ints("pads") {
+                    None => (0, 0),
+                    Some(pads) if pads.len() == 2 * spatial && pads.iter().all(|&pad| pad >= 0) => {
+                        if spatial == 1 {
+                            if d == 0 { (0, 0) } else { (pads[0] as usize, pads[1] as usize) }
+                        } else {
+                            (pads[d] as usize, pads[d + 2] as usize)
+                        }
+                    }
+                    Some(pads) => return Err(format!("bad pads {:?}", pads)),
+                },
+                "VALID" => (0, 0),
+                "SAME_UPPER" | "SAME_LOWER" => {
+                    let wanted = input[d].div_ceil(strides[d]);
+                    let total = ((wanted - 1) * strides[d] + span(d)).saturating_sub(input[d]);
+                    if auto_pad == "SAME_UPPER" { (total / 2, total - total / 2) } else { (total - total / 2, total / 2) }
+                }
+                other => return Err(format!("unknown auto_pad {}", other)),
+            };
+            
+            let padded = input[d] + begin + end;
+            if padded < span(d) {
+                return Err(format!("kernel {:?} is larger than the padded input", kernel));
+            }
+            let mut size = if ceil_mode {
+                (padded - span(d)).div_ceil(strides[d]) + 1
+            } else {
+                (padded - span(d)) / strides[d] + 1
+            };
+            // A window may not start in the end padding
+            if ceil_mode && (size - 1) * strides[d] >= input[d] + begin {
+                size -= 1;
+            }
+            pads[d] = begin;
+            output[d] = size;
+        }
+        
+        Ok(Self { kernel, strides, dilations, pads, output })
+    }
+    
+    // Input index of a kernel tap, if it falls inside the input
+    fn source(&self, d: usize, out: usize, tap: usize, size: usize) -> Option<usize> {
+        (out * self.strides[d] + tap * self.dilations[d]).checked_sub(self.pads[d]).filter(|&index| index < size)
+    }
+}
+
+// Split an N x C x [H x] W shape into batch, channels and a 2-D plane
+fn planes(tensor: &Tensor, op: &str) -> Result<(usize, usize, [usize; 2], usize), String> {
+    match tensor.shape.as_slice() {
+        &[n, c, w] => Ok((n, c, [1, w], 1)),
+        &[n, c, h, w] => Ok((n, c, [h, w], 2)),
+        shape => Err(format!("{} supports 1-D and 2-D inputs, not shape {:?}", op, shape)),
+    }
+}
+
+fn output_shape(n: usize, c: usize, output: [usize; 2], spatial: usize) -> Vec<usize> {
+    if spatial == 1 { vec![n, c, output[1]] } else { vec![n, c, output[0], output[1]] }
+}
+
+fn conv(node: &Node, x: &Tensor, w: &Tensor, bias: Option<&Tensor>) -> Result<Tensor, String> {
+    let (n, c, input, spatial) = planes(x, "Conv")?;
+    let group = node.int("group", 1).max(1) as usize;
+    let (m, kernel) = match w.shape.as_slice() {
+        &[m, cg, kw] if spatial == 1 && cg * group == c => (m, [1, kw]),
+        &[m, cg, kh, kw] if spatial == 2 && cg * group == c => (m, [kh, kw]),
+        shape => return Err(format!("weights {:?} do not match input {:?} in {} groups", shape, x.shape, group)),
+    };
+    if m % group != 0 {
+        return Err(format!("{} filters cannot be split into {} groups", m, group));
+    }
+    if bias.is_some_and(|bias| bias.data.len() != m) {
+        return Err(format!("bias does not have {} values", m));
+    }
+    let window = Window::new(node, input, kernel, spatial, false)?;
+    
+    let (cg, mg) = (c / group, m / group);
+    let plane = input[0] * input[1];
+    let positions = window.output[0] * window.output[1];
+    let taps = cg * window.kernel[0] * window.kernel[1];
+    let mut columns = vec![0.0; taps * positions];
+    let mut data = vec![0.0; n * m * positions];
+    
+    for batch in 0..n {
+        for g in 0..group {
+            // im2col: one row per kernel tap, one column per output position
+            for channel in 0..cg {
+                let source = &x.data[(batch * c + g * cg + channel) * plane..][..plane];
+                for ky in 0..window.kernel[0] {
+                    for kx in 0..window.kernel[1] {
+                        let row = (channel * window.kernel[0] + ky) * window.kernel[1] + kx;
+                        let row = &mut columns[row * positions..][..positions];
+                        for oy in 0..window.output[0] {
+                            let iy = window.source(0, oy, ky, input[0]);
+                            for ox in 0..window.output[1] {
+                                let ix = window.source(1, ox, kx, input[1]);
+                                row[oy * window.output[1] + ox] = match (iy, ix) {
+                                    (Some(iy), Some(ix)) => source[iy * input[1] + ix],
+                                    _ => 0.0,
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+            
+            let filters = &w.data[g * mg * taps..][..mg * taps];
+            let out = &mut data[(batch * m + g * mg) * positions..][..mg * positions];
+            kernels::matmul(filters, &columns, out, mg, taps, positions);
+            if let Some(bias) = bias {
+                for (filter, out) in out.chunks_exact_mut(positions).enumerate() {
+                    let value = bias.data[g * mg + filter];
+                    out.iter_mut().for_each(|out| *out += value);
+                }
+            }
+        }
+    }
+    Tensor::new(output_shape(n, m, window.output, spatial), data)
+}
+
+fn pool(node: &Node, x: &Tensor) -> Result<Tensor, String> {
+    let (n, c, input, spatial) = planes(x, &node.op)?;
+    let kernel = match node.ints("kernel_shape") {
+        Some(kernel) if kernel.len() == spatial && kernel.iter().all(|&size| size > 0) => {
+            if spatial == 1 { [1, kernel[0] as usize] } else { [kernel[0] as usize, kernel[1] as usize] }
+        }
+        kernel => return Err(format!("bad kernel_shape {:?}", kernel)),
+    };
+    let window = Window::new(node, input, kernel, spatial, node.int("ceil_mode", 0) != 0)?;
+    let max = node.op == "MaxPool";
+    let include_pad = node.int("count_include_pad", 0) != 0;
+    
+    let plane = input[0] * input[1];
+    let mut data = Vec::with_capacity(n * c * window.output[0] * window.output[1]);
+    for source in x.data.chunks_exact(plane.max(1)).take(n * c) {
+        for oy in 0..window.output[0] {
+            for ox in 0..window.output[1] {
+                let mut value = if max { f32::NEG_INFINITY } else { 0.0 };
+                let mut count = 0;
+                let mut padded = 0;
+                for ky in 0..window.kernel[0] {
+                    for kx in 0..window.kernel[1] {
+                        let (Some(iy), Some(ix)) = (window.source(0, oy, ky, input[0]), window.source(1, ox, kx, input[1])) else {
+                            padded += 1;
+                            continue;
+                        };
+                        let x = source[iy * input[1] + ix];
+                        value = if max { value.max(x) } else { value + x };
+                        count += 1;
+                    }
+                }
+                if !max {
+                    // Windows cut short by ceil_mode past the padding are not counted whole
+                    let divisor = if include_pad { count + padded.min(window.kernel[0] * window.kernel[1] - count) } else { count };
+                    value /= divisor.max(1) as f32;
+                }
+                data.push(value);
+            }
+        }
+    }
+    Tensor::new(output_shape(n, c, window.output, spatial), data)
+}
+
+fn global_pool(node: &Node, x: &Tensor) -> Result<Tensor, String> {
+    if x.shape.len() < 3 {
+        return Err(format!("{} needs an N x C x ... input, not {:?}", node.op, x.shape));
+    }
+    let plane: usize = x.shape[2..].iter().product();
+    let data = x.data.chunks_exact(plane.max(1))
+        .map(|values| {
+            if node.op == "GlobalMaxPool" {
+                values.iter().copied().fold(f32::NEG_INFINITY, f32::max)
+            } else {
+                values.iter().sum::<f32>() / plane as f32
+            }
+        })
+        .collect();
+    let mut shape = x.shape.clone();
+    shape[2..].iter_mut().for_each(|dimension| *dimension = 1);
+    Tensor::new(shape, data)
+}
+
+fn batch_norm(node: &Node, x: &Tensor, scale: &Tensor, bias: &Tensor, mean: &Tensor, var: &Tensor) -> Result<Tensor, String> {
+    if x.shape.len() < 2 {
+        return Err(format!("BatchNormalization needs an N x C x ... input, not {:?}", x.shape));
+    }
+    let channels = x.shape[1];
+    if [scale, bias, mean, var].iter().any(|tensor| tensor.data.len() != channels) {
+        return Err(format!("BatchNormalization parameters do not have {} values", channels));
+    }
+    let epsilon = node.float("epsilon", 1e-5);
+    let plane: usize = x.shape[2..].iter().product();
+    
+    let mut out = x.clone();
+    for (index, values) in out.data.chunks_exact_mut(plane.max(1)).enumerate() {
+        let channel = index % channels;
+        let factor = scale.data[channel] / (var.data[channel] + epsilon).sqrt();
+        let shift = bias.data[channel] - mean.data[channel] * factor;
+        values.iter_mut().for_each(|value| *value = *value * factor + shift);
+    }
+    Ok(out)
+}
+
+fn softmax(node: &Node, x: &Tensor, opset: i64) -> Result<Tensor, String> {
+    let rank = x.shape.len();
+    // Before opset 13 the input was flattened to 2-D at the axis
+    let (outer, size, inner) = if opset < 13 {
+        let axis = axis(node.int("axis", 1), rank)?;
+        (x.shape[..axis].iter().product::<usize>(), x.shape[axis..].iter().product::<usize>(), 1)
+    } else {
+        let axis = axis(node.int("axis", -1), rank)?;
+        (x.shape[..axis].iter().product(), x.shape.get(axis).copied().unwrap_or(1), x.shape[(axis + 1).min(rank)..].iter().product())
+    };
+    let log = node.op == "LogSoftmax";
+    
+    let mut out = x.clone();
+    for block in out.data.chunks_exact_mut((size * inner).max(1)).take(outer) {
+        for lane in 0..inner {
+            let max = (0..size).map(|i| block[i * inner + lane]).fold(f32::NEG_INFINITY, f32::max);
+            let sum: f32 = (0..size).map(|i| (block[i * inner + lane] - max).exp()).sum();
+            for i in 0..size {
+                let value = &mut block[i * inner + lane];
+                *value = if log { *value - max - sum.ln() } else { (*value - max).exp() / sum };
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn reshape(x: &Tensor, shape: &[i64], allow_zero: bool) -> Result<Tensor, String> {
+    let mut unknown = None;
+    let mut dims = Vec::with_capacity(shape.len());
+    for (i, &dimension) in shape.iter().enumerate() {
+        match dimension {
+            -1 if unknown.is_none() => {
+                unknown = Some(i);
+                dims.push(1);
+            }
+            0 if !allow_zero => dims.push(*x.shape.get(i).ok_or_else(|| format!("no dimension {} to copy", i))?),
+            dimension if dimension >= 0 => dims.push(dimension as usize),
+            _ => return Err(format!("bad target shape {:?}", shape)),
+        }
+    }
+    if let Some(i) = unknown {
+        let known: usize = dims.iter().product();
+        if known == 0 || !x.data.len().is_multiple_of(known) {
+            return Err(format!("cannot reshape {:?} to {:?}", x.shape, shape));
+        }
+        dims[i] = x.data.len() / known;
+    }
+    x.reshaped(dims).map_err(|_| format!("cannot reshape {:?} to {:?}", x.shape, shape))
+}
+
+fn transpose(x: &Tensor, perm: Option<Vec<i64>>) -> Result<Tensor, String> {
+    let rank = x.shape.len();
+    let perm: Vec<usize> = match perm {
+        Some(perm) => perm.iter().map(|&axis| axis as usize).collect(),
+        None => (0..rank).rev().collect(),
+    };
+    let mut sorted = perm.clone();
+    sorted.sort_unstable();
+    if sorted != (0..rank).collect::<Vec<_>>() {
+        return Err(format!("bad permutation {:?} for rank {}", perm, rank));
+    }
+    
+    let shape: Vec<usize> = perm.iter().map(|&axis| x.shape[axis]).collect();
+    let source = broadcast_strides(&x.shape, &x.shape);
+    let strides: Vec<usize> = perm.iter().map(|&axis| source[axis]).collect();
+    let mut data = Vec::with_capacity(x.data.len());
+    for_each_offset(&shape, &strides, &strides, |i, _| data.push(x.data[i]));
+    Tensor::new(shape, data)
+}
+
+fn concat(parts: &[&Tensor], axis_attr: i64) -> Result<Tensor, String> {
+    let first = parts.first().ok_or("Concat needs inputs")?;
+    let rank = first.shape.len();
+    let axis = axis(axis_attr, rank)?;
+    for part in parts {
+        if part.shape.len() != rank || part.shape.iter().enumerate().any(|(d, &size)| d != axis && size != first.shape[d]) {
+            return Err(format!("cannot concatenate {:?} with {:?}", first.shape, part.shape));
+        }
+    }
+    
+    let outer: usize = first.shape[..axis].iter().product();
+    let inner: usize = first.shape[axis + 1..].iter().product();
+    let mut shape = first.shape.clone();
+    shape[axis] = parts.iter().map(|part| part.shape[axis]).sum();
+    let mut data = Vec::with_capacity(parts.iter().map(|part| part.data.len()).sum());
+    for block in 0..outer {
+        for part in parts {
+            let size = part.shape[axis] * inner;
+            data.extend_from_slice(&part.data[block * size..][..size]);
+        }
+    }
+    Tensor::new(shape, data)
+}
+
+fn squeeze(x: &Tensor, axes: Option<Vec<i64>>) -> Result<Tensor, String> {
+    let rank = x.shape.len();
+    let axes = match axes {
+        Some(axes) => axes.iter().map(|&a| axis(a, rank)).collect::<Result<Vec<usize>, String>>()?,
+        None => (0..rank).filter(|&d| x.shape[d] == 1).collect(),
+    };
+    if let Some(&d) = axes.iter().find(|&&d| x.shape[d] != 1) {
+        return Err(format!("cannot squeeze dimension {} of size {}", d, x.shape[d]));
+    }
+    let shape = x.shape.iter().enumerate().filter(|(d, _)| !axes.contains(d)).map(|(_, &size)| size).collect();
+    x.reshaped(shape)
+}
+
+fn unsqueeze(x: &Tensor, axes: &[i64]) -> Result<Tensor, String> {
+    let rank = x.shape.len() + axes.len();
+    let mut axes = axes.iter().map(|&a| axis(a, rank)).collect::<Result<Vec<usize>, String>>()?;
+    axes.sort_unstable();
+    let mut shape = x.shape.clone();
+    for d in axes {
+        shape.insert(d, 1);
+    }
+    x.reshaped(shape)
+}
+
+fn constant(node: &Node) -> Result<Tensor, String> {
+    let attribute = |name: &str| node.attributes.get(name);
+    if let Some(tensor) = attribute("value").and_then(|attribute| attribute.t.as_ref()) {
+        return Tensor::from_proto(tensor, None);
+    }
+    if let Some(attribute) = attribute("value_float") {
+        return Ok(Tensor::scalar(attribute.f));
+    }
+    if let Some(attribute) = attribute("value_int") {
+        return Ok(Tensor::scalar(attribute.i as f32));
+    }
+    if let Some(attribute) = attribute("value_floats") {
+        return Tensor::new(vec![attribute.floats.len()], attribute.floats.clone());
+    }
+    if let Some(attribute) = attribute("value_ints") {
+        return Tensor::new(vec![attribute.ints.len()], attribute.ints.iter().map(|&value| value as f32).collect());
+    }
+    Err("Constant has no supported value".to_string())
+}
+
+fn gather(data: &Tensor, indices: &Tensor, axis_attr: i64) -> Result<Tensor, String> {
+    let axis = axis(axis_attr, data.shape.len())?;
+    let size = data.shape[axis];
+    let outer: usize = data.shape[..axis].iter().product();
+    let inner: usize = data.shape[axis + 1..].iter().product();
+    let positions = indices.ints().into_iter()
+        .map(|index| {
+            let resolved = if index < 0 { index + size as i64 } else { index };
+            usize::try_from(resolved).ok().filter(|&i| i < size).ok_or_else(|| format!("index {} is out of range", index))
+        })
+        .collect::<Result<Vec<usize>, String>>()?;
+    
+    let mut out = Vec::with_capacity(outer * positions.len() * inner);
+    for block in 0..outer {
+        for &index in &positions {
+            out.extend_from_slice(&data.data[(block * size + index) * inner..][..inner]);
+        }
+    }
+    let mut shape = data.shape[..axis].to_vec();
+    shape.extend_from_slice(&indices.shape);
+    shape.extend_from_slice(&data.shape[axis + 1..]);
+    Tensor::new(shape, out)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(shape: &[usize], data: &[f32]) -> Tensor {
+        Tensor::new(shape.to_vec(), data.to_vec()).unwrap()
+    }
+
+    fn int(name: &str, value: i64) -> AttributeProto {
+        AttributeProto { name: name.to_string(), i: value, ..Default::default() }
+    }
+
+    fn float(name: &str, value: f32) -> AttributeProto {
+        AttributeProto { name: name.to_string(), f: value, ..Default::default() }
+    }
+
+    fn ints(name: &str, values: &[i64]) -> AttributeProto {
+        AttributeProto { name: name.to_string(), ints: values.to_vec(), ..Default::default() }
+    }
+
+    fn node(op: &str, attributes: Vec<AttributeProto>) -> Node {
+        Node::from_proto(&NodeProto {
+            op_type: op.to_string(),
+            output: vec!["y".to_string()],
+            attribute: attributes,
+            ..Default::default()
+        })
+    }
+
+    // Run `op` at `opset` and take its one output
+    fn eval(op: &str, attributes: Vec<AttributeProto>, inputs: &[Option<&Tensor>], opset: i64) -> Result<Tensor, String> {
+        run(&node(op, attributes), inputs, opset).map(|mut outputs| outputs.remove(0))
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} vs {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} vs {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn broadcasts_elementwise_operators() {
+        let a = tensor(&[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let row = tensor(&[3], &[10.0, 20.0, 30.0]);
+        let sum = eval("Add", vec![], &[Some(&a), Some(&row)], 13).unwrap();
+        assert_eq!(sum, tensor(&[2, 3], &[11.0, 22.0, 33.0, 14.0, 25.0, 36.0]));
+
+        let column = tensor(&[2, 1], &[1.0, 2.0]);
+        let product = eval("Mul", vec![], &[Some(&column), Some(&row)], 13).unwrap();
+        assert_eq!(product, tensor(&[2, 3], &[10.0, 20.0, 30.0, 20.0, 40.0, 60.0]));
+
+        let half = Tensor::scalar(2.0);
+        assert_eq!(eval("Div", vec![], &[Some(&a), Some(&half)], 13).unwrap().data, [0.5, 1.0, 1.5, 2.0, 2.5, 3.0]);
+        assert_eq!(eval("Sub", vec![], &[Some(&half), Some(&row)], 13).unwrap().data, [-8.0, -18.0, -28.0]);
+
+        let wrong = tensor(&[2], &[1.0, 2.0]);
+        assert!(eval("Add", vec![], &[Some(&a), Some(&wrong)], 13).is_err());
+        assert_eq!(eval("Add", vec![], &[Some(&a)], 13).unwrap_err(), "missing input 1");
+    }
+
+    #[test]
+    fn applies_activations() {
+        let x = tensor(&[4], &[-2.0, -0.5, 0.0, 3.0]);
+        assert_eq!(eval("Relu", vec![], &[Some(&x)], 13).unwrap().data, [0.0, 0.0, 0.0, 3.0]);
+        assert_eq!(eval("LeakyRelu", vec![float("alpha", 0.5)], &[Some(&x)], 13).unwrap().data, [-1.0, -0.25, 0.0, 3.0]);
+        assert_close(&eval("Sigmoid", vec![], &[Some(&x)], 13).unwrap().data, &[0.11920292, 0.37754067, 0.5, 0.95257413]);
+        assert_close(&eval("Tanh", vec![], &[Some(&x)], 13).unwrap().data, &[-0.9640276, -0.46211716, 0.0, 0.9950548]);
+
+        // Clip bounds moved from attributes to inputs in opset 11
+        let old = eval("Clip", vec![float("min", -1.0), float("max", 1.0)], &[Some(&x)], 6).unwrap();
+        assert_eq!(old.data, [-1.0, -0.5, 0.0, 1.0]);
+        let (min, max) = (Tensor::scalar(-1.0), Tensor::scalar(1.0));
+        assert_eq!(eval("Clip", vec![], &[Some(&x), Some(&min), Some(&max)], 13).unwrap().data, old.data);
+        assert_eq!(eval("Clip", vec![], &[Some(&x), None, Some(&max)], 13).unwrap().data, [-2.0, -0.5, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn multiplies_matrices() {
+        let a = tensor(&[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let b = tensor(&[3, 2], &[7.0, 8.0, 9.0, 10.0, 11.0, 12.0]);
+        assert_eq!(eval("MatMul", vec![], &[Some(&a), Some(&b)], 13).unwrap(), tensor(&[2, 2], &[58.0, 64.0, 139.0, 154.0]));
+
+        // A 1-D left operand is a row vector and drops out of the shape
+        let v = tensor(&[3], &[1.0, 2.0, 3.0]);
+        assert_eq!(eval("MatMul", vec![], &[Some(&v), Some(&b)], 13).unwrap(), tensor(&[2], &[58.0, 64.0]));
+        // A 1-D right operand is a column vector
+        assert_eq!(eval("MatMul", vec![], &[Some(&a), Some(&v)], 13).unwrap(), tensor(&[2], &[14.0, 32.0]));
+
+        // Batch dimensions broadcast
+        let batch = tensor(&[2, 1, 2], &[1.0, 0.0, 0.0, 1.0]);
+        let m = tensor(&[2, 2], &[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(
+            eval("MatMul", vec![], &[Some(&batch), Some(&m)], 13).unwrap(),
+            tensor(&[2, 1, 2], &[1.0, 2.0, 3.0, 4.0])
+        );
+
+        assert!(eval("MatMul", vec![], &[Some(&a), Some(&a)], 13).is_err());
+    }
+
+    #[test]
+    fn runs_gemm_with_transposes_and_bias() {
+        // A is given transposed
+        let a = tensor(&[3, 2], &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        let b = tensor(&[3, 2], &[7.0, 8.0, 9.0, 10.0, 11.0, 12.0]);
+        let c = tensor(&[2], &[1.0, 2.0]);
+        let attributes = vec![int("transA", 1), float("alpha", 2.0), float("beta", 0.5)];
+        let y = eval("Gemm", attributes, &[Some(&a), Some(&b), Some(&c)], 13).unwrap();
+        assert_eq!(y, tensor(&[2, 2], &[116.5, 129.0, 278.5, 309.0]));
+
+        let bt = tensor(&[2, 3], &[7.0, 9.0, 11.0, 8.0, 10.0, 12.0]);
+        let y = eval("Gemm", vec![int("transA", 1), int("transB", 1)], &[Some(&a), Some(&bt)], 13).unwrap();
+        assert_eq!(y.data, [58.0, 64.0, 139.0, 154.0]);
+
+        let bad_bias = tensor(&[3], &[1.0, 2.0, 3.0]);
+        assert!(eval("Gemm", vec![int("transA", 1)], &[Some(&a), Some(&b), Some(&bad_bias)], 13).is_err());
+    }
+
+    #[test]
+    fn convolves_with_padding_strides_and_groups() {
+        let x = tensor(&[1, 1, 3, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        let w = tensor(&[1, 1, 2, 2], &[1.0; 4]);
+        let bias = tensor(&[1], &[1.0]);
+        let y = eval("Conv", vec![], &[Some(&x), Some(&w), Some(&bias)], 13).unwrap();
+        assert_eq!(y, tensor(&[1, 1, 2, 2], &[13.0, 17.0, 25.0, 29.0]));
+
+        let attributes = vec![ints("pads", &[1, 1, 1, 1]), ints("strides", &[2, 2])];
+        let y = eval("Conv", attributes, &[Some(&x), Some(&w), Some(&bias)], 13).unwrap();
+        assert_eq!(y, tensor(&[1, 1, 2, 2], &[2.0, 6.0, 12.0, 29.0]));
+
+        // SAME_UPPER keeps the spatial size, padding at the end
+        let y = eval("Conv", vec![AttributeProto { name: "auto_pad".to_string(), s: b"SAME_UPPER".to_vec(), ..Default::default() }], &[Some(&x), Some(&w)], 13).unwrap();
+        assert_eq!(y, tensor(&[1, 1, 3, 3], &[12.0, 16.0, 9.0, 24.0, 28.0, 15.0, 15.0, 17.0, 9.0]));
+
+        // Depthwise, over one spatial dimension
+        let x = tensor(&[1, 2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let w = tensor(&[2, 1, 1], &[2.0, 3.0]);
+        let y = eval("Conv", vec![int("group", 2)], &[Some(&x), Some(&w)], 13).unwrap();
+        assert_eq!(y, tensor(&[1, 2, 3], &[2.0, 4.0, 6.0, 12.0, 15.0, 18.0]));
+
+        let w = tensor(&[1, 3, 1], &[1.0; 3]);
+        assert!(eval("Conv", vec![], &[Some(&x), Some(&w)], 13).is_err());
+    }
+
+    #[test]
+    fn pools() {
+        let x = tensor(&[1, 1, 4, 4], &(1..=16).map(|value| value as f32).collect::<Vec<_>>());
+        let attributes = vec![ints("kernel_shape", &[2, 2]), ints("strides", &[2, 2])];
+        assert_eq!(eval("MaxPool", attributes, &[Some(&x)], 13).unwrap(), tensor(&[1, 1, 2, 2], &[6.0, 8.0, 14.0, 16.0]));
+
+        // Padding counts toward the average only when asked to
+        let x = tensor(&[1, 1, 2, 2], &[1.0, 2.0, 3.0, 4.0]);
+        let attributes = || vec![ints("kernel_shape", &[2, 2]), ints("pads", &[1, 1, 1, 1])];
+        let y = eval("AveragePool", attributes(), &[Some(&x)], 13).unwrap();
+        assert_eq!(y.shape, [1, 1, 3, 3]);
+        assert_eq!((y.data[0], y.data[1], y.data[4]), (1.0, 1.5, 2.5));
+        let mut with_pad = attributes();
+        with_pad.push(int("count_include_pad", 1));
+        let y = eval("AveragePool", with_pad, &[Some(&x)], 13).unwrap();
+        assert_eq!((y.data[0], y.data[1], y.data[4]), (0.25, 0.75, 2.5));
+
+        // ceil_mode keeps the partial window at the end
+        let x = tensor(&[1, 1, 5], &[1.0, 2.0, 3.0, 4.0, 5.0]);
+        let attributes = |ceil| vec![ints("kernel_shape", &[2]), ints("strides", &[2]), int("ceil_mode", ceil)];
+        assert_eq!(eval("MaxPool", attributes(0), &[Some(&x)], 13).unwrap().data, [2.0, 4.0]);
+        assert_eq!(eval("MaxPool", attributes(1), &[Some(&x)], 13).unwrap().data, [2.0, 4.0, 5.0]);
+
+        let x = tensor(&[1, 2, 2, 1], &[1.0, 2.0, 3.0, 5.0]);
+        assert_eq!(eval("GlobalAveragePool", vec![], &[Some(&x)], 13).unwrap(), tensor(&[1, 2, 1, 1], &[1.5, 4.0]));
+        assert_eq!(eval("GlobalMaxPool", vec![], &[Some(&x)], 13).unwrap().data, [2.0, 5.0]);
+
+        let mut indices = node("MaxPool", vec![ints("kernel_shape", &[1])]);
+        indices.outputs.push("indices".to_string());
+        assert!(run(&indices, &[Some(&x)], 13).is_err());
+    }
+
+    #[test]
+    fn normalizes_batches() {
+        let x = tensor(&[1, 2, 1, 1], &[1.0, 2.0]);
+        let scale = tensor(&[2], &[2.0, 1.0]);
+        let bias = tensor(&[2], &[0.0, 1.0]);
+        let mean = tensor(&[2], &[1.0, 0.0]);
+        let var = tensor(&[2], &[4.0, 1.0]);
+        let inputs = [Some(&x), Some(&scale), Some(&bias), Some(&mean), Some(&var)];
+        assert_eq!(eval("BatchNormalization", vec![float("epsilon", 0.0)], &inputs, 13).unwrap().data, [0.0, 3.0]);
+
+        let short = tensor(&[1], &[1.0]);
+        let inputs = [Some(&x), Some(&short), Some(&bias), Some(&mean), Some(&var)];
+        assert!(eval("BatchNormalization", vec![], &inputs, 13).is_err());
+    }
+
+    #[test]
+    fn takes_softmax_along_an_axis() {
+        let x = tensor(&[1, 3], &[1.0, 2.0, 3.0]);
+        assert_close(&eval("Softmax", vec![], &[Some(&x)], 13).unwrap().data, &[0.09003057, 0.24472847, 0.66524096]);
+        assert_close(&eval("LogSoftmax", vec![], &[Some(&x)], 13).unwrap().data, &[-2.407606, -1.407606, -0.407606]);
+
+        // Before opset 13 everything from the axis on is one row
+        let x = tensor(&[1, 2, 2], &[0.0; 4]);
+        assert_eq!(eval("Softmax", vec![], &[Some(&x)], 11).unwrap().data, [0.25; 4]);
+        assert_eq!(eval("Softmax", vec![], &[Some(&x)], 13).unwrap().data, [0.5; 4]);
+        assert_eq!(eval("Softmax", vec![int("axis", 1)], &[Some(&x)], 13).unwrap().data, [0.5; 4]);
+        assert!(eval("Softmax", vec![int("axis", 3)], &[Some(&x)], 13).is_err());
+    }
+
+    #[test]
+    fn reshapes() {
+        let x = tensor(&[2, 3, 2], &[0.0; 12]);
+        let shape = tensor(&[2], &[0.0, -1.0]);
+        assert_eq!(eval("Reshape", vec![], &[Some(&x), Some(&shape)], 13).unwrap().shape, [2, 6]);
+        // Opset 1 took the shape as an attribute
+        assert_eq!(eval("Reshape", vec![ints("shape", &[-1])], &[Some(&x)], 1).unwrap().shape, [12]);
+        let bad = tensor(&[2], &[5.0, -1.0]);
+        assert!(eval("Reshape", vec![], &[Some(&x), Some(&bad)], 13).is_err());
+        let two_unknown = tensor(&[2], &[-1.0, -1.0]);
+        assert!(eval("Reshape", vec![], &[Some(&x), Some(&two_unknown)], 13).is_err());
+
+        assert_eq!(eval("Flatten", vec![], &[Some(&x)], 13).unwrap().shape, [2, 6]);
+        assert_eq!(eval("Flatten", vec![int("axis", 0)], &[Some(&x)], 13).unwrap().shape, [1, 12]);
+        assert_eq!(eval("Flatten", vec![int("axis", -1)], &[Some(&x)], 13).unwrap().shape, [6, 2]);
+
+        let x = tensor(&[1, 3, 1], &[1.0, 2.0, 3.0]);
+        assert_eq!(eval("Squeeze", vec![], &[Some(&x)], 13).unwrap().shape, [3]);
+        let axes = tensor(&[1], &[-1.0]);
+        assert_eq!(eval("Squeeze", vec![], &[Some(&x), Some(&axes)], 13).unwrap().shape, [1, 3]);
+        assert!(eval("Squeeze", vec![ints("axes", &[1])], &[Some(&x)], 11).is_err());
+        let v = tensor(&[3], &[1.0, 2.0, 3.0]);
+        assert_eq!(eval("Unsqueeze", vec![ints("axes", &[0, -1])], &[Some(&v)], 11).unwrap().shape, [1, 3, 1]);
+        assert!(eval("Unsqueeze", vec![], &[Some(&v)], 13).is_err());
+    }
+
+    #[test]
+    fn transposes_and_concatenates() {
+        let x = tensor(&[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(eval("Transpose", vec![], &[Some(&x)], 13).unwrap(), tensor(&[3, 2], &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]));
+        let cube = tensor(&[1, 2, 3], &x.data);
+        let y = eval("Transpose", vec![ints("perm", &[2, 0, 1])], &[Some(&cube)], 13).unwrap();
+        assert_eq!(y, tensor(&[3, 1, 2], &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]));
+        assert!(eval("Transpose", vec![ints("perm", &[0, 0])], &[Some(&x)], 13).is_err());
+
+        let a = tensor(&[2, 1], &[1.0, 2.0]);
+        let b = tensor(&[2, 2], &[3.0, 4.0, 5.0, 6.0]);
+        let y = eval("Concat", vec![int("axis", 1)], &[Some(&a), Some(&b)], 13).unwrap();
+        assert_eq!(y, tensor(&[2, 3], &[1.0, 3.0, 4.0, 2.0, 5.0, 6.0]));
+        assert!(eval("Concat", vec![int("axis", 0)], &[Some(&a), Some(&b)], 13).is_err());
+    }
+
+    #[test]
+    fn gathers_and_reads_shapes() {
+        let x = tensor(&[2, 3], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let indices = tensor(&[2], &[2.0, -3.0]);
+        let y = eval("Gather", vec![int("axis", 1)], &[Some(&x), Some(&indices)], 13).unwrap();
+        assert_eq!(y, tensor(&[2, 2], &[3.0, 1.0, 6.0, 4.0]));
+        let row = Tensor::scalar(1.0);
+        assert_eq!(eval("Gather", vec![], &[Some(&x), Some(&row)], 13).unwrap(), tensor(&[3], &[4.0, 5.0, 6.0]));
+        let out_of_range = tensor(&[1], &[3.0]);
+        assert!(eval("Gather", vec![int("axis", 1)], &[Some(&x), Some(&out_of_range)], 13).is_err());
+
+        let cube = tensor(&[2, 3, 4], &[0.0; 24]);
+        assert_eq!(eval("Shape", vec![], &[Some(&cube)], 13).unwrap().data, [2.0, 3.0, 4.0]);
+        assert_eq!(eval("Shape", vec![int("start", 1), int("end", -1)], &[Some(&cube)], 15).unwrap().data, [3.0]);
+    }
+
+    #[test]
+    fn builds_constants() {
+        let values = AttributeProto { name: "value_ints".to_string(), ints: vec![1, -2], ..Default::default() };
+        assert_eq!(eval("Constant", vec![values], &[], 13).unwrap(), tensor(&[2], &[1.0, -2.0]));
+        let value = AttributeProto {
+            name: "value".to_string(),
+            t: Some(TensorProto { dims: vec![1], data_type: proto::FLOAT, float_data: vec![7.0], ..Default::default() }),
+            ..Default::default()
+        };
+        assert_eq!(eval("Constant", vec![value], &[], 13).unwrap(), tensor(&[1], &[7.0]));
+        assert!(eval("Constant", vec![], &[], 13).is_err());
+    }
+
+    #[test]
+    fn decodes_tensor_data() {
+        let raw: Vec<u8> = [1.5f32, -2.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+        let floats = TensorProto { dims: vec![2], data_type: proto::FLOAT, raw_data: raw, ..Default::default() };
+        assert_eq!(Tensor::from_proto(&floats, None).unwrap(), tensor(&[2], &[1.5, -2.0]));
+
+        let int8 = TensorProto { dims: vec![2], data_type: proto::INT8, raw_data: vec![0xFF, 3], ..Default::default() };
+        assert_eq!(Tensor::from_proto(&int8, None).unwrap().data, [-1.0, 3.0]);
+        let int64 = TensorProto { dims: vec![1, 2], data_type: proto::INT64, int64_data: vec![4, -5], ..Default::default() };
+        assert_eq!(Tensor::from_proto(&int64, None).unwrap(), tensor(&[1, 2], &[4.0, -5.0]));
+
+        let short = TensorProto { dims: vec![3], data_type: proto::FLOAT, float_data: vec![1.0], ..Default::default() };
+        assert!(Tensor::from_proto(&short, None).is_err());
+        let negative = TensorProto { dims: vec![-1], data_type: proto::FLOAT, ..Default::default() };
+        assert_eq!(Tensor::from_proto(&negative, None).unwrap_err(), "bad dimension -1");
+        let bfloat16 = TensorProto { dims: vec![1], data_type: 16, raw_data: vec![0, 0], ..Default::default() };
+        assert!(Tensor::from_proto(&bfloat16, None).is_err());
+    }
+
+    #[test]
+    fn keeps_external_data_beside_the_model() {
+        let external = |location: &str| TensorProto {
+            name: "w".to_string(),
+            dims: vec![1],
+            data_type: proto::FLOAT,
+            data_location: 1,
+            external_data: vec![proto::StringStringEntryProto { key: "location".to_string(), value: location.to_string() }],
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir();
+        for location in ["../weights.bin", "/etc/passwd", "a/../../b"] {
+            assert_eq!(
+                Tensor::from_proto(&external(location), Some(&dir)).unwrap_err(),
+                "tensor 'w' has an unsafe external data location"
+            );
+        }
+        assert!(Tensor::from_proto(&external("weights.bin"), None).is_err());
+    }
+
+    #[test]
+    fn refuses_unknown_operators() {
+        let x = Tensor::scalar(1.0);
+        assert_eq!(eval("Einsum", vec![], &[Some(&x)], 13).unwrap_err(), "operator Einsum is not supported");
+    }
+}
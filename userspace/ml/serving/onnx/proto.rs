@@ -0,0 +1,254 @@
+// Messages of onnx.proto3 that inference needs. Fields left out here, such
+// as subgraphs and training info, are skipped when a model is decoded.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ModelProto {
+    #[prost(int64, tag = "1")]
+    pub ir_version: i64,
+    #[prost(string, tag = "2")]
+    pub producer_name: String,
+    #[prost(string, tag = "3")]
+    pub producer_version: String,
+    #[prost(string, tag = "4")]
+    pub domain: String,
+    #[prost(int64, tag = "5")]
+    pub model_version: i64,
+    #[prost(string, tag = "6")]
+    pub doc_string: String,
+    #[prost(message, optional, tag = "7")]
+    pub graph: Option<GraphProto>,
+    #[prost(message, repeated, tag = "8")]
+    pub opset_import: Vec<OperatorSetIdProto>,
+    #[prost(message, repeated, tag = "14")]
+    pub metadata_props: Vec<StringStringEntryProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OperatorSetIdProto {
+    // Empty for the default "ai.onnx" domain
+    #[prost(string, tag = "1")]
+    pub domain: String,
+    #[prost(int64, tag = "2")]
+    pub version: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StringStringEntryProto {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GraphProto {
+    #[prost(message, repeated, tag = "1")]
+    pub node: Vec<NodeProto>,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(message, repeated, tag = "5")]
+    pub initializer: Vec<TensorProto>,
+    #[prost(message, repeated, tag = "11")]
+    pub input: Vec<ValueInfoProto>,
+    #[prost(message, repeated, tag = "12")]
+    pub output: Vec<ValueInfoProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeProto {
+    #[prost(string, repeated, tag = "1")]
+    pub input: Vec<String>,
+    #[prost(string, repeated, tag = "2")]
+    pub output: Vec<String>,
+    #[prost(string, tag = "3")]
+    pub name: String,
+    #[prost(string, tag = "4")]
+    pub op_type: String,
+    #[prost(message, repeated, tag = "5")]
+    pub attribute: Vec<AttributeProto>,
+    #[prost(string, tag = "7")]
+    pub domain: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttributeProto {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(float, tag = "2")]
+    pub f: f32,
+    #[prost(int64, tag = "3")]
+    pub i: i64,
+    #[prost(bytes = "vec", tag = "4")]
+    pub s: Vec<u8>,
+    #[prost(message, optional, tag = "5")]
+    pub t: Option<TensorProto>,
+    #[prost(float, repeated, tag = "7")]
+    pub floats: Vec<f32>,
+    #[prost(int64, repeated, tag = "8")]
+    pub ints: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorProto {
+    #[prost(int64, repeated, tag = "1")]
+    pub dims: Vec<i64>,
+    #[prost(int32, tag = "2")]
+    pub data_type: i32,
+    #[prost(float, repeated, tag = "4")]
+    pub float_data: Vec<f32>,
+    // Also holds the narrower integer types and booleans
+    #[prost(int32, repeated, tag = "5")]
+    pub int32_data: Vec<i32>,
+    #[prost(int64, repeated, tag = "7")]
+    pub int64_data: Vec<i64>,
+    #[prost(string, tag = "8")]
+    pub name: String,
+    // Little-endian values, used in place of the typed fields when set
+    #[prost(bytes = "vec", tag = "9")]
+    pub raw_data: Vec<u8>,
+    #[prost(double, repeated, tag = "10")]
+    pub double_data: Vec<f64>,
+    // Also holds uint32
+    #[prost(uint64, repeated, tag = "11")]
+    pub uint64_data: Vec<u64>,
+    #[prost(message, repeated, tag = "13")]
+    pub external_data: Vec<StringStringEntryProto>,
+    // 1 when the data lives in `external_data`
+    #[prost(int32, tag = "14")]
+    pub data_location: i32,
+}
+
+// TensorProto.DataType
+pub const FLOAT: i32 = 1;
+pub const UINT8: i32 = 2;
+pub const INT8: i32 = 3;
+pub const UINT16: i32 = 4;
+pub const INT16: i32 = 5;
+pub const INT32: i32 = 6;
+pub const INT64: i32 = 7;
+pub const BOOL: i32 = 9;
+pub const DOUBLE: i32 = 11;
+pub const UINT32: i32 = 12;
+pub const UINT64: i32 = 13;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ValueInfoProto {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, tag = "2")]
+    pub r#type: Option<TypeProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TypeProto {
+    // Sequence, map and optional types are not served
+    #[prost(message, optional, tag = "1")]
+    pub tensor_type: Option<TensorTypeProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorTypeProto {
+    #[prost(int32, tag = "1")]
+    pub elem_type: i32,
+    #[prost(message, optional, tag = "2")]
+    pub shape: Option<TensorShapeProto>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorShapeProto {
+    #[prost(message, repeated, tag = "1")]
+    pub dim: Vec<Dimension>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Dimension {
+    #[prost(oneof = "DimensionValue", tags = "1, 2")]
+    pub value: Option<DimensionValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum DimensionValue {
+    #[prost(int64, tag = "1")]
+    DimValue(i64),
+    // A named dimension, such as the batch size, fixed only at run time
+    #[prost(string, tag = "2")]
+    DimParam(String),
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn decodes_a_tensor_from_the_wire() {
+        let mut bytes = vec![
+            0x0A, 0x02, 0x02, 0x03, // dims: packed [2, 3]
+            0x10, 0x01, // data_type: FLOAT
+            0x42, 0x01, b'w', // name
+            0x78, 0x2A, // field 15, unknown here
+            0x4A, 0x04, // raw_data
+        ];
+        bytes.extend_from_slice(&1.0f32.to_le_bytes());
+        let tensor = TensorProto::decode(bytes.as_slice()).unwrap();
+        assert_eq!(tensor.dims, [2, 3]);
+        assert_eq!(tensor.data_type, FLOAT);
+        assert_eq!(tensor.name, "w");
+        assert_eq!(tensor.raw_data, 1.0f32.to_le_bytes());
+        assert_eq!(tensor.data_location, 0);
+    }
+
+    #[test]
+    fn decodes_named_and_fixed_dimensions() {
+        let bytes = [
+            0x0A, 0x03, 0x12, 0x01, b'N', // dim_param "N"
+            0x0A, 0x02, 0x08, 0x03, // dim_value 3
+            0x0A, 0x00, // neither
+        ];
+        let shape = TensorShapeProto::decode(bytes.as_slice()).unwrap();
+        let dims: Vec<Option<DimensionValue>> = shape.dim.into_iter().map(|dim| dim.value).collect();
+        assert_eq!(dims, [Some(DimensionValue::DimParam("N".to_string())), Some(DimensionValue::DimValue(3)), None]);
+    }
+
+    #[test]
+    fn round_trips_a_model() {
+        let model = ModelProto {
+            ir_version: 8,
+            producer_name: "test".to_string(),
+            opset_import: vec![OperatorSetIdProto { domain: String::new(), version: 13 }],
+            graph: Some(GraphProto {
+                name: "g".to_string(),
+                node: vec![NodeProto {
+                    input: vec!["x".to_string()],
+                    output: vec!["y".to_string()],
+                    op_type: "Softmax".to_string(),
+                    attribute: vec![AttributeProto { name: "axis".to_string(), i: -1, ..Default::default() }],
+                    ..Default::default()
+                }],
+                input: vec![ValueInfoProto {
+                    name: "x".to_string(),
+                    r#type: Some(TypeProto {
+                        tensor_type: Some(TensorTypeProto {
+                            elem_type: FLOAT,
+                            shape: Some(TensorShapeProto {
+                                dim: vec![Dimension { value: Some(DimensionValue::DimValue(4)) }],
+                            }),
+                        }),
+                    }),
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let decoded = ModelProto::decode(model.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, model);
+        assert_eq!(decoded.graph.unwrap().node[0].attribute[0].i, -1);
+    }
+
+    #[test]
+    fn refuses_truncated_input() {
+        // A length-delimited field claiming more bytes than follow
+        assert!(ModelProto::decode([0x3A, 0x10, 0x0A].as_slice()).is_err());
+        // A varint that never ends
+        assert!(TensorProto::decode([0x10, 0xFF, 0xFF].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+// Model repository watcher. The repository is a directory with one
+// subdirectory per model and one per version inside that, each version
+// holding a model.onnx:
+//
+//   models/
+//     resnet50/
+//       1/model.onnx
+//       2/model.onnx
+//
+// It is polled for changes. A new or changed version is loaded beside the
+// ones serving and only then put in the registry, so requests never wait on
+// a load or see a half-loaded model.
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use super::{model_key, onnx::OnnxModel, ABTestManager, Model, ModelEndpoint, ServerError};
+
+const MODEL_FILE: &str = "model.onnx";
+
+// Model file of each (model, version) found, and when it last changed
+type Listing = HashMap<(String, String), (PathBuf, SystemTime)>;
+
+pub struct RepositoryWatcher {
+    root: PathBuf,
+    models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+    traffic: Arc<Mutex<ABTestManager>>,
+    // Share of unversioned traffic a newly found version starts with
+    canary_traffic: f32,
+    // Modification time of each version's model file when last looked at,
+    // whether it loaded or not, so a broken file is retried only once changed
+    seen: HashMap<(String, String), SystemTime>,
+}
+
+impl RepositoryWatcher {
+    pub fn new(
+        root: PathBuf,
+        models: Arc<RwLock<HashMap<String, ModelEndpoint>>>,
+        traffic: Arc<Mutex<ABTestManager>>,
+        canary_traffic: f32,
+    ) -> Self {
+        Self {
+            root,
+            models,
+            traffic,
+            canary_traffic: canary_traffic.clamp(0.0, 1.0),
+            seen: HashMap::new(),
+        }
+    }
+    
+    // Bring the registry in line with the repository
+    pub fn scan(&mut self) -> Result<(), ServerError> {
+        let found = list(&self.root)?;
+        
+        let gone: Vec<(String, String)> = self.seen.keys().filter(|key| !found.contains_key(*key)).cloned().collect();
+        for (name, version) in gone {
+            self.seen.remove(&(name.clone(), version.clone()));
+            self.unload(&name, &version);
+        }
+        
+        // Oldest first, so the newest version of a model ends up the canary
+        let mut changed: Vec<((String, String), (PathBuf, SystemTime))> = found.into_iter()
+            .filter(|(key, (_, modified))| self.seen.get(key) != Some(modified))
+            .collect();
+        changed.sort_by(|((a_name, a_version), _), ((b_name, b_version), _)| {
+            a_name.cmp(b_name).then_with(|| compare_versions(a_version, b_version))
+        });
+        for ((name, version), (path, modified)) in changed {
+            self.seen.insert((name.clone(), version.clone()), modified);
+            match OnnxModel::load(&path) {
+                Ok(model) => self.install(&name, &version, Box::new(model)),
+                Err(e) => eprintln!("Cannot load {} version {}: {}", name, version, e),
+            }
+        }
+        Ok(())
+    }
+    
+    // Keep scanning every `interval` from a thread of its own
+    pub fn spawn(mut self, interval: Duration) {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = self.scan() {
+                eprintln!("Model repository scan failed: {}", e);
+            }
+        });
+    }
+    
+    fn install(&self, name: &str, version: &str, model: Box<dyn Model>) {
+        let key = model_key(name, Some(version));
+        {
+            let mut models = self.models.write().unwrap();
+            if let Some(endpoint) = models.get_mut(&key) {
+                endpoint.replace_model(model);
+                println!("Reloaded model {} version {}", name, version);
+                return;
+            }
+            models.insert(key, ModelEndpoint::new(name.to_string(), version.to_string(), model));
+        }
+        
+        let mut traffic = self.traffic.lock().unwrap();
+        match traffic.rollout(name) {
+            None => {
+                traffic.create_experiment(name.to_string(), version.to_string(), version.to_string(), 1.0);
+                println!("Loaded model {} version {}", name, version);
+            },
+            Some(rollout) => {
+                let newest = rollout.canary.as_deref().unwrap_or(&rollout.stable);
+                if compare_versions(version, newest) == Ordering::Greater {
+                    traffic.start_canary(name, version.to_string(), self.canary_traffic);
+                    println!(
+                        "Loaded model {} version {} as a canary taking {:.0}% of traffic",
+                        name, version, self.canary_traffic * 100.0
+                    );
+                } else {
+                    println!("Loaded model {} version {}", name, version);
+                }
+            },
+        }
+    }
+    
+    fn unload(&self, name: &str, version: &str) {
+        let mut models = self.models.write().unwrap();
+        models.remove(&model_key(name, Some(version)));
+        let fallback = models.values()
+            .filter(|endpoint| endpoint.name == name)
+            .map(|endpoint| endpoint.version.clone())
+            .max_by(|a, b| compare_versions(a, b));
+        self.traffic.lock().unwrap().retire(name, version, fallback);
+        println!("Unloaded model {} version {}", name, version);
+    }
+}
+
+// Every version in the repository
+fn list(root: &Path) -> Result<Listing, ServerError> {
+    let entries = fs::read_dir(root)
+        .map_err(|e| ServerError::ConfigError(format!("cannot read model repository {}: {}", root.display(), e)))?;
+    
+    let mut found = HashMap::new();
+    for model in entries.flatten() {
+        let Some(name) = directory_name(&model.path()) else {
+            continue;
+        };
+        let Ok(versions) = fs::read_dir(model.path()) else {
+            continue;
+        };
+        for version in versions.flatten() {
+            let Some(version_name) = directory_name(&version.path()) else {
+                continue;
+            };
+            let path = version.path().join(MODEL_FILE);
+            if let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                found.insert((name.clone(), version_name), (path, modified));
+            }
+        }
+    }
+    Ok(found)
+}
+
+// The name of a directory, skipping hidden ones such as editor droppings
+fn directory_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    (path.is_dir() && !name.starts_with('.')).then(|| name.to_string())
+}
+
+// Numeric versions compare as numbers, anything else as text
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // An ONNX model whose graph copies input x to output y, encoded by hand:
+    // ModelProto { graph: GraphProto { node: [Identity x -> y], input: [x],
+    // output: [y] }, opset_import: [{ version: 13 }] }
+    const IDENTITY_MODEL: &[u8] = &[
+        0x3A, 0x1C, // graph
+        0x0A, 0x10, // node
+        0x0A, 0x01, b'x', 0x12, 0x01, b'y', 0x22, 0x08, b'I', b'd', b'e', b'n', b't', b'i', b't', b'y',
+        0x5A, 0x03, 0x0A, 0x01, b'x', // input
+        0x62, 0x03, 0x0A, 0x01, b'y', // output
+        0x42, 0x02, 0x10, 0x0D, // opset_import
+    ];
+    
+    struct Repository {
+        root: PathBuf,
+        watcher: RepositoryWatcher,
+    }
+    
+    impl Repository {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("model-repository-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            let models = Arc::new(RwLock::new(HashMap::new()));
+            let traffic = Arc::new(Mutex::new(ABTestManager::new()));
+            let watcher = RepositoryWatcher::new(root.clone(), models, traffic, 0.25);
+            Self { root, watcher }
+        }
+        
+        // Write a version's model file, last modified at `modified` seconds
+        // past the epoch
+        fn write(&self, model: &str, version: &str, bytes: &[u8], modified: u64) {
+            let dir = self.root.join(model).join(version);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(MODEL_FILE), bytes).unwrap();
+            let file = fs::File::options().write(true).open(dir.join(MODEL_FILE)).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified)).unwrap();
+        }
+        
+        fn remove(&self, model: &str, version: &str) {
+            fs::remove_dir_all(self.root.join(model).join(version)).unwrap();
+        }
+        
+        fn scan(&mut self) {
+            self.watcher.scan().unwrap();
+        }
+        
+        fn loaded(&self) -> Vec<String> {
+            let mut keys: Vec<String> = self.watcher.models.read().unwrap().keys().cloned().collect();
+            keys.sort();
+            keys
+        }
+        
+        // Stable version, then canary and its share
+        fn rollout(&self, model: &str) -> Option<(String, Option<String>, f32)> {
+            let traffic = self.watcher.traffic.lock().unwrap();
+            traffic.rollout(model).map(|rollout| (rollout.stable, rollout.canary, rollout.canary_traffic))
+        }
+    }
+    
+    impl Drop for Repository {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+    
+    #[test]
+    fn loads_versions_and_starts_the_newest_as_a_canary() {
+        let mut repository = Repository::new("canary");
+        repository.write("m", "1", IDENTITY_MODEL, 100);
+        repository.write("m", "2", IDENTITY_MODEL, 100);
+        repository.scan();
+        assert_eq!(repository.loaded(), ["m:1", "m:2"]);
+        assert_eq!(repository.rollout("m"), Some(("1".to_string(), Some("2".to_string()), 0.25)));
+        
+        // A newer version takes over as the canary, by number rather than text
+        repository.write("m", "10", IDENTITY_MODEL, 100);
+        repository.scan();
+        assert_eq!(repository.rollout("m"), Some(("1".to_string(), Some("10".to_string()), 0.25)));
+        
+        // An older one is only loaded
+        repository.write("m", "0", IDENTITY_MODEL, 100);
+        repository.scan();
+        assert_eq!(repository.loaded(), ["m:0", "m:1", "m:10", "m:2"]);
+        assert_eq!(repository.rollout("m"), Some(("1".to_string(), Some("10".to_string()), 0.25)));
+    }
+    
+    #[test]
+    fn unloads_removed_versions() {
+        let mut repository = Repository::new("unload");
+        repository.write("m", "1", IDENTITY_MODEL, 100);
+        repository.write("m", "2", IDENTITY_MODEL, 100);
+        repository.scan();
+        
+        // Losing the canary sends all traffic back to the stable version
+        repository.remove("m", "2");
+        repository.scan();
+        assert_eq!(repository.loaded(), ["m:1"]);
+        assert_eq!(repository.rollout("m"), Some(("1".to_string(), None, 0.0)));
+        
+        // Losing the last version forgets the model
+        repository.remove("m", "1");
+        repository.scan();
+        assert!(repository.loaded().is_empty());
+        assert_eq!(repository.rollout("m"), None);
+    }
+    
+    #[test]
+    fn reloads_a_changed_version_in_place() {
+        let mut repository = Repository::new("reload");
+        repository.write("m", "1", IDENTITY_MODEL, 100);
+        repository.scan();
+        let model = |repository: &Repository| Arc::as_ptr(&repository.watcher.models.read().unwrap()["m:1"].model);
+        let before = model(&repository);
+        
+        // Unchanged files are left alone
+        repository.scan();
+        assert_eq!(model(&repository), before);
+        
+        repository.write("m", "1", IDENTITY_MODEL, 200);
+        repository.scan();
+        assert_ne!(model(&repository), before);
+        assert_eq!(repository.rollout("m"), Some(("1".to_string(), None, 0.0)));
+    }
+    
+    #[test]
+    fn retries_a_broken_model_only_once_it_changes() {
+        let mut repository = Repository::new("broken");
+        repository.write("m", "1", b"not a model", 100);
+        repository.scan();
+        assert!(repository.loaded().is_empty());
+        assert_eq!(repository.watcher.seen.len(), 1);
+        
+        repository.write("m", "1", IDENTITY_MODEL, 200);
+        repository.scan();
+        assert_eq!(repository.loaded(), ["m:1"]);
+    }
+    
+    #[test]
+    fn skips_hidden_and_incomplete_directories() {
+        let mut repository = Repository::new("hidden");
+        repository.write(".staging", "1", IDENTITY_MODEL, 100);
+        repository.write("m", ".1", IDENTITY_MODEL, 100);
+        fs::create_dir_all(repository.root.join("m").join("2")).unwrap();
+        fs::write(repository.root.join("stray.onnx"), IDENTITY_MODEL).unwrap();
+        repository.scan();
+        assert!(repository.loaded().is_empty());
+    }
+    
+    #[test]
+    fn fails_on_a_missing_repository() {
+        let repository = Repository::new("missing");
+        let mut watcher = RepositoryWatcher::new(
+            repository.root.join("absent"),
+            Arc::clone(&repository.watcher.models),
+            Arc::clone(&repository.watcher.traffic),
+            0.1,
+        );
+        assert!(matches!(watcher.scan(), Err(ServerError::ConfigError(_))));
+    }
+    
+    #[test]
+    fn compares_versions_numerically_when_it_can() {
+        assert_eq!(compare_versions("2", "10"), Ordering::Less);
+        assert_eq!(compare_versions("10", "10"), Ordering::Equal);
+        assert_eq!(compare_versions("beta", "alpha"), Ordering::Greater);
+        // Mixed versions fall back to text
+        assert_eq!(compare_versions("10", "9a"), Ordering::Less);
+    }
+}
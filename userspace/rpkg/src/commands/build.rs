@@ -2,8 +2,8 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    spec_file: &str, output: Option<String>, no_deps: bool, sign: bool,
-    config: &Config,
+    _spec_file: &str, _output: Option<String>, _no_deps: bool, _sign: bool,
+    _config: &Config,
 ) -> Result<(), Box<dyn Error>> {
     println!("Build command not yet implemented");
     Ok(())
@@ -2,9 +2,9 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    all: bool, keep: Option<usize>,
-    config: &Config,
-    yes: bool,
+    _all: bool, _keep: Option<usize>,
+    _config: &Config,
+    _yes: bool,
 ) -> Result<(), Box<dyn Error>> {
     println!("Clean command not yet implemented");
     Ok(())
@@ -1,22 +1,22 @@
 use std::error::Error;
 use crate::config::Config;
 
-pub fn show(config: &Config) -> Result<(), Box<dyn Error>> {
+pub fn show(_config: &Config) -> Result<(), Box<dyn Error>> {
     println!("Config show command not yet implemented");
     Ok(())
 }
 
-pub fn set(key: &str, value: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+pub fn set(_key: &str, _value: &str, _config: &Config) -> Result<(), Box<dyn Error>> {
     println!("Config set command not yet implemented");
     Ok(())
 }
 
-pub fn get(key: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+pub fn get(_key: &str, _config: &Config) -> Result<(), Box<dyn Error>> {
     println!("Config get command not yet implemented");
     Ok(())
 }
 
-pub fn reset(force: bool, config: &Config) -> Result<(), Box<dyn Error>> {
+pub fn reset(_force: bool, _config: &Config) -> Result<(), Box<dyn Error>> {
     println!("Config reset command not yet implemented");
     Ok(())
 }
@@ -2,8 +2,8 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    package: &str, reverse: bool, max_depth: Option<usize>,
-    config: &Config,
+    _package: &str, _reverse: bool, _max_depth: Option<usize>,
+    _config: &Config,
 ) -> Result<(), Box<dyn Error>> {
     println!("Deptree command not yet implemented");
     Ok(())
@@ -2,8 +2,8 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    packages: Vec<String>, dest: Option<String>,
-    config: &Config,
+    _packages: Vec<String>, _dest: Option<String>,
+    _config: &Config,
 ) -> Result<(), Box<dyn Error>> {
     println!("Download command not yet implemented");
     Ok(())
@@ -1,10 +1,205 @@
+use chrono::{DateTime, Local};
+use colored::*;
+use serde::Serialize;
 use std::error::Error;
 use crate::config::Config;
+use crate::database::{Dependency, FileKind, IndexEntry, LocalDatabase, OptionalDependency};
+use crate::display::{format_size, print_json};
+use crate::utils::PackageManager;
+
+#[derive(Serialize)]
+struct Installed {
+    explicit: bool,
+    install_date: i64,
+}
+
+/// One of its dependencies, and what meets it
+#[derive(Serialize)]
+struct DependencyReport {
+    requirement: String,
+    /// The installed package that meets it
+    installed: Option<String>,
+    /// or else the newest one a repository has
+    available: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    name: String,
+    version: String,
+    description: String,
+    repository: String,
+    installed: Option<Installed>,
+    depends: Vec<String>,
+    optional: Vec<OptionalDependency>,
+    provides: Vec<String>,
+    conflicts: Vec<String>,
+    replaces: Vec<String>,
+    size: u64,
+    installed_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dependencies: Option<Vec<DependencyReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    required_by: Option<Vec<String>>,
+}
+
+fn field(label: &str, value: impl std::fmt::Display) {
+    println!("{} : {}", format!("{:<16}", label).bold(), value);
+}
+
+fn list(items: &[String]) -> String {
+    if items.is_empty() {
+        String::from("None")
+    } else {
+        items.join("  ")
+    }
+}
 
 pub fn run(
     package: &str, files: bool, deps: bool, reverse_deps: bool,
+    json: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Info command not yet implemented");
+    let pm = PackageManager::new(config)?;
+    let local = LocalDatabase::load(&config.general.db_path)?;
+    let candidates = pm.candidates()?;
+    
+    // What is installed, or else the newest version on offer
+    let installed = local.get(package);
+    let (entry, repository): (&IndexEntry, &str) = match installed {
+        Some(installed) => (&installed.entry, &installed.repository),
+        None => candidates.iter()
+            .filter(|candidate| candidate.entry.name == package)
+            .max_by(|a, b| a.entry.version.cmp(&b.entry.version).then(b.priority.cmp(&a.priority)))
+            .map(|candidate| (&candidate.entry, candidate.repository.as_str()))
+            .ok_or_else(|| format!("package '{}' was not found", package))?,
+    };
+    
+    let mut report = Report {
+        name: entry.name.clone(),
+        version: entry.version.to_string(),
+        description: entry.description.clone(),
+        repository: repository.to_string(),
+        installed: installed.map(|installed| Installed {
+            explicit: installed.explicit,
+            install_date: installed.install_date,
+        }),
+        depends: entry.depends.clone(),
+        optional: entry.optional.clone(),
+        provides: entry.provides.clone(),
+        conflicts: entry.conflicts.clone(),
+        replaces: entry.replaces.clone(),
+        size: entry.size,
+        installed_size: entry.installed_size,
+        files: None,
+        dependencies: None,
+        required_by: None,
+    };
+    
+    if files {
+        let records = if installed.is_some() { local.files(&entry.name)? } else { Vec::new() };
+        report.files = Some(records.iter()
+            .map(|record| match record.kind {
+                FileKind::Directory => format!("/{}/", record.path.display()),
+                _ => format!("/{}", record.path.display()),
+            })
+            .collect());
+    }
+    if deps {
+        let mut dependencies = Vec::new();
+        for spec in &entry.depends {
+            let dependency = Dependency::parse(spec)?;
+            let met = local.packages.iter()
+                .find(|package| dependency.matches(&package.entry))
+                .map(|package| package.entry.to_string());
+            let available = match met {
+                Some(_) => None,
+                None => candidates.iter()
+                    .filter(|candidate| dependency.matches(&candidate.entry))
+                    .max_by_key(|candidate| candidate.entry.version)
+                    .map(|candidate| candidate.entry.to_string()),
+            };
+            dependencies.push(DependencyReport { requirement: spec.clone(), installed: met, available });
+        }
+        report.dependencies = Some(dependencies);
+    }
+    if reverse_deps {
+        report.required_by = Some(local.packages.iter()
+            .filter(|other| other.entry.depends.iter().any(|spec| {
+                Dependency::parse(spec).is_ok_and(|dependency| dependency.matches(entry))
+            }))
+            .map(|other| other.entry.name.clone())
+            .collect());
+    }
+    
+    if json {
+        return print_json(&report);
+    }
+    
+    field("Name", report.name.bold());
+    field("Version", &report.version);
+    field("Description", &report.description);
+    field("Repository", &report.repository);
+    field("Provides", list(&report.provides));
+    field("Depends On", list(&report.depends));
+    let optional: Vec<String> = report.optional.iter()
+        .map(|optional| if optional.reason.is_empty() {
+            optional.name.clone()
+        } else {
+            format!("{} ({})", optional.name, optional.reason)
+        })
+        .collect();
+    field("Optional Deps", list(&optional));
+    field("Conflicts With", list(&report.conflicts));
+    field("Replaces", list(&report.replaces));
+    field("Download Size", format_size(report.size));
+    field("Installed Size", format_size(report.installed_size));
+    match &report.installed {
+        Some(installed) => {
+            let date = DateTime::from_timestamp(installed.install_date, 0)
+                .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default();
+            field("Install Date", date);
+            field("Install Reason", if installed.explicit {
+                "Explicitly installed"
+            } else {
+                "Installed as a dependency of another package"
+            });
+        }
+        None => field("Install Reason", "Not installed".dimmed()),
+    }
+    
+    if let Some(dependencies) = &report.dependencies {
+        println!("\n{}", "Dependencies:".bold());
+        for dependency in dependencies {
+            let status = match (&dependency.installed, &dependency.available) {
+                (Some(installed), _) => format!("{} (installed)", installed).green(),
+                (None, Some(available)) => format!("{} (available)", available).yellow(),
+                (None, None) => "not available".red(),
+            };
+            println!("  {} {} → {}", "•".dimmed(), dependency.requirement, status);
+        }
+    }
+    if let Some(required_by) = &report.required_by {
+        println!("\n{}", "Required By:".bold());
+        if required_by.is_empty() {
+            println!("  {}", "Nothing installed".dimmed());
+        }
+        for name in required_by {
+            println!("  {} {}", "•".dimmed(), name);
+        }
+    }
+    if let Some(files) = &report.files {
+        println!("\n{}", "Files:".bold());
+        if files.is_empty() {
+            println!("  {}", "No file list; the package is not installed, or was installed before file lists were kept".dimmed());
+        }
+        for file in files {
+            println!("  {}", file);
+        }
+    }
+    
     Ok(())
 }
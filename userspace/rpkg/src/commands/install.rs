@@ -1,40 +1,89 @@
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
-use humansize::{format_size, BINARY};
 use std::error::Error;
 use crate::config::Config;
-use crate::utils::{confirm_action, PackageManager};
-use crate::display::format_package_list;
+use crate::utils::{confirm_action, PackageManager, Resolution};
+use crate::display::format_size;
+use crate::exit::Exit;
+use crate::transaction::Transaction;
+use crate::triggers;
 
+/// How to carry out a resolution
+pub struct Options {
+    /// The transaction's name in `rpkg rollback`
+    pub operation: &'static str,
+    pub as_deps: bool,
+    pub allow_unsigned: bool,
+    pub download_only: bool,
+    pub explain: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     packages: Vec<String>,
     no_deps: bool,
     as_deps: bool,
     reinstall: bool,
+    allow_unsigned: bool,
+    explain: bool,
     config: &Config,
     yes: bool,
 ) -> Result<(), Box<dyn Error>> {
-    let mut pm = PackageManager::new(config)?;
+    let pm = PackageManager::new(config)?;
     
     println!("{} Resolving dependencies...", "::".blue().bold());
     
-    let resolution = pm.resolve_install(&packages, no_deps)?;
+    let mut resolution = pm.resolve_install(&packages, no_deps, reinstall)?;
     
-    if resolution.to_install.is_empty() && !reinstall {
-        println!("{} All requested packages are already installed", "::".green().bold());
-        return Ok(());
+    if !yes && resolution.conflicts.is_empty() && !resolution.suggestions.is_empty() {
+        println!("\n{} Optional dependencies:", "::".blue().bold());
+        for suggestion in &resolution.suggestions {
+            println!("  {} {} - {}", 
+                "•".dimmed(), 
+                suggestion.name.yellow(),
+                suggestion.reason.dimmed()
+            );
+        }
+        if confirm_action("Install optional dependencies too?")? {
+            let optional: Vec<String> = resolution.suggestions.iter().map(|suggestion| suggestion.name.clone()).collect();
+            let mut wanted = packages.clone();
+            wanted.extend(optional.iter().cloned());
+            resolution = pm.resolve_install(&wanted, no_deps, reinstall)?;
+            // They are installed as dependencies of what asked for them
+            resolution.explicit.retain(|name| !optional.contains(name) || packages.contains(name));
+        }
+        resolution.suggestions.clear();
     }
     
+    let options = Options {
+        operation: "install",
+        as_deps,
+        allow_unsigned,
+        download_only: false,
+        explain,
+    };
+    execute(&pm, &resolution, &options, config, yes)
+}
+
+/// Show a resolution and, once confirmed, download, unpack and install its
+/// packages in one transaction
+pub fn execute(
+    pm: &PackageManager,
+    resolution: &Resolution,
+    options: &Options,
+    config: &Config,
+    yes: bool,
+) -> Result<(), Box<dyn Error>> {
     if !resolution.conflicts.is_empty() {
         eprintln!("{} Package conflicts detected:", "Error:".red().bold());
         for conflict in &resolution.conflicts {
-            eprintln!("  {} {} conflicts with {}", 
-                "•".red(), 
-                conflict.package1.yellow(), 
-                conflict.package2.yellow()
-            );
+            eprintln!("  {} {}", "•".red(), conflict.reason);
         }
-        return Err("Cannot proceed due to conflicts".into());
+        return Err(Exit::Conflict(String::from("Cannot proceed due to conflicts")).into());
+    }
+    
+    if resolution.to_install.is_empty() && resolution.to_upgrade.is_empty() && resolution.to_remove.is_empty() {
+        return Err(Exit::NothingToDo(String::from("All requested packages are already installed")).into());
     }
     
     let download_size = resolution.total_download_size();
@@ -66,13 +115,31 @@ pub fn run(
         }
     }
     
+    if !resolution.to_remove.is_empty() {
+        println!("\n{} ({}):", "Packages to remove".red(), resolution.to_remove.len());
+        for pkg in &resolution.to_remove {
+            println!("  {} {}-{}", 
+                "•".red(), 
+                pkg.name.bold(), 
+                pkg.version.to_string().dimmed()
+            );
+        }
+    }
+    
+    if options.explain {
+        println!("\n{}", "Why:".bold());
+        for (package, why) in &resolution.explanation {
+            println!("  {} {} - {}", "•".dimmed(), package.bold(), why);
+        }
+    }
+    
     println!("\n{} {}", 
         "Total download size:".bold(), 
-        format_size(download_size, BINARY).cyan()
+        format_size(download_size).cyan()
     );
     println!("{} {}", 
         "Total installed size:".bold(), 
-        format_size(install_size, BINARY).cyan()
+        format_size(install_size).cyan()
     );
     
     if !yes && !confirm_action("Proceed with installation?")? {
@@ -80,7 +147,11 @@ pub fn run(
         return Ok(());
     }
     
-    let pb = ProgressBar::new(resolution.to_install.len() as u64);
+    let packages: Vec<_> = resolution.to_install.iter()
+        .chain(resolution.to_upgrade.iter().map(|(_, new)| new))
+        .collect();
+    
+    let pb = ProgressBar::new(packages.len() as u64);
     pb.set_style(
         ProgressStyle::default_bar()
             .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}")
@@ -90,17 +161,27 @@ pub fn run(
     
     println!("\n{} Downloading packages...", "::".blue().bold());
     
-    for pkg in &resolution.to_install {
+    let mut archives = Vec::new();
+    for pkg in &packages {
         pb.set_message(format!("Downloading {}", pkg.name));
-        pm.download_package(pkg)?;
+        archives.push(pm.download_package(pkg, options.allow_unsigned)?);
         pb.inc(1);
     }
     
     pb.finish_with_message("Downloads complete");
     
+    if options.download_only {
+        println!("\n{} Downloaded {} package(s) to {}",
+            "✓".green().bold(),
+            packages.len(),
+            config.cache.dir
+        );
+        return Ok(());
+    }
+    
     println!("\n{} Installing packages...", "::".blue().bold());
     
-    let pb = ProgressBar::new(resolution.to_install.len() as u64);
+    let pb = ProgressBar::new(packages.len() as u64);
     pb.set_style(
         ProgressStyle::default_bar()
             .template("{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}")
@@ -108,24 +189,39 @@ pub fn run(
             .progress_chars("#>-")
     );
     
-    for pkg in &resolution.to_install {
+    let mut transaction = Transaction::begin(config, options.operation)?;
+    let mut contents = Vec::new();
+    for (pkg, archive) in packages.iter().zip(&archives) {
+        pb.set_message(format!("Unpacking {}", pkg.name));
+        contents.push(transaction.stage(&pkg.name, archive)?);
+    }
+    pb.set_message("Applying");
+    let journal = transaction.commit()?;
+    
+    for (pkg, paths) in packages.iter().zip(&contents) {
         pb.set_message(format!("Installing {}", pkg.name));
         
-        if as_deps {
+        if options.as_deps || !resolution.explicit.contains(&pkg.name) {
             pm.install_as_dependency(pkg)?;
         } else {
             pm.install_package(pkg)?;
         }
+        pm.record_files(pkg, paths)?;
         
         pb.inc(1);
     }
+    for pkg in &resolution.to_remove {
+        pm.forget_package(pkg)?;
+    }
     
     pb.finish_with_message("Installation complete");
+    triggers::run(config, &journal)?;
     
     println!("\n{} Successfully installed {} package(s)", 
         "✓".green().bold(),
-        resolution.to_install.len()
+        packages.len()
     );
+    println!("{} Transaction {}; undo it with 'rpkg rollback {}'", "::".blue().bold(), journal.id.bold(), journal.id);
     
     if !resolution.suggestions.is_empty() {
         println!("\n{} Optional dependencies:", "Tip:".cyan().bold());
@@ -139,4 +235,4 @@ pub fn run(
     }
     
     Ok(())
-}
\ No newline at end of file
+}
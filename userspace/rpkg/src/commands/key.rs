@@ -0,0 +1,86 @@
+use colored::*;
+use prettytable::{Table, row};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use crate::config::Config;
+use crate::display::print_success;
+use crate::signing::{self, KeyId, Keyring, PublicKey, SecretKey};
+use crate::utils::confirm_action;
+
+pub fn add(file: &str, config: &Config, yes: bool) -> Result<(), Box<dyn Error>> {
+    let key = PublicKey::parse(&fs::read_to_string(file)?)?;
+    let mut keyring = Keyring::load(&config.security.keyring_dir)?;
+    
+    println!("{} Key {} {}", "::".blue().bold(), key.id.to_string().bold(), key.comment.dimmed());
+    if !yes && !confirm_action("Trust this key to sign repositories and packages?")? {
+        println!("{} Key not added", "::".yellow().bold());
+        return Ok(());
+    }
+    
+    let id = key.id;
+    keyring.add(key)?;
+    print_success(&format!("Key {} added", id));
+    Ok(())
+}
+
+pub fn list(config: &Config) -> Result<(), Box<dyn Error>> {
+    let keyring = Keyring::load(&config.security.keyring_dir)?;
+    
+    if keyring.keys().next().is_none() {
+        println!("{} No trusted keys in {}", "::".yellow().bold(), config.security.keyring_dir);
+    } else {
+        let mut table = Table::new();
+        table.add_row(row![b->"Key ID", b->"Comment"]);
+        for key in keyring.keys() {
+            table.add_row(row![key.id, key.comment]);
+        }
+        table.printstd();
+    }
+    
+    if !keyring.revoked().is_empty() {
+        println!("\n{}", "Revoked:".red().bold());
+        for id in keyring.revoked() {
+            println!("  {} {}", "•".red(), id);
+        }
+    }
+    Ok(())
+}
+
+pub fn revoke(key_id: &str, config: &Config, yes: bool) -> Result<(), Box<dyn Error>> {
+    let id = KeyId::parse(key_id).ok_or_else(|| format!("'{}' is not a key ID", key_id))?;
+    let mut keyring = Keyring::load(&config.security.keyring_dir)?;
+    
+    if !yes && !confirm_action(&format!("Revoke key {}? Nothing it signed will be accepted again", id))? {
+        println!("{} Key not revoked", "::".yellow().bold());
+        return Ok(());
+    }
+    
+    keyring.revoke(id)?;
+    print_success(&format!("Key {} revoked", id));
+    Ok(())
+}
+
+
+pub fn generate(output: &str) -> Result<(), Box<dyn Error>> {
+    let secret_path = format!("{}.key", output);
+    let public_path = format!("{}.pub", output);
+    
+    let password = signing::password("Password for the secret key (empty for none): ", true)?;
+    
+    let key = SecretKey::generate()?;
+    let text = key.to_file(&password)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&secret_path)
+        .map_err(|e| format!("{}: {}", secret_path, e))?;
+    file.write_all(text.as_bytes())?;
+    fs::write(&public_path, key.public_key().to_file())?;
+    
+    print_success(&format!("Key {} written to {} and {}", key.id, secret_path, public_path));
+    println!("{} Sign a repository with 'rpkg repo-build <dir> --key {}', and trust it elsewhere with 'rpkg key add {}'",
+        "::".blue().bold(),
+        secret_path,
+        public_path
+    );
+    Ok(())
+}
\ No newline at end of file
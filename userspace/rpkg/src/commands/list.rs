@@ -1,10 +1,95 @@
+use colored::*;
+use prettytable::{Table, row};
+use serde::Serialize;
 use std::error::Error;
 use crate::config::Config;
+use crate::database::{Dependency, InstalledPackage, LocalDatabase};
+use crate::display::print_json;
+use crate::utils::PackageManager;
+
+// Installed as a dependency, and needed by nothing installed now
+fn is_orphan(package: &InstalledPackage, local: &LocalDatabase) -> bool {
+    !package.explicit && !local.packages.iter().any(|other| {
+        other.entry.depends.iter().any(|spec| {
+            Dependency::parse(spec).is_ok_and(|dependency| dependency.matches(&package.entry))
+        })
+    })
+}
+
+#[derive(Serialize)]
+struct Packages<T> {
+    packages: Vec<T>,
+}
+
+#[derive(Serialize)]
+struct Listed<'a> {
+    name: &'a str,
+    version: String,
+    repository: &'a str,
+    explicit: bool,
+    install_date: i64,
+}
+
+fn list_outdated(json: bool, config: &Config) -> Result<(), Box<dyn Error>> {
+    let pm = PackageManager::new(config)?;
+    let outdated = pm.outdated()?;
+    if json {
+        return print_json(&Packages { packages: outdated });
+    }
+    if outdated.is_empty() {
+        println!("{} All packages are up to date", "::".green().bold());
+        return Ok(());
+    }
+    
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Installed", b->"Newest", b->"Upgrade to", b->"Lock"]);
+    for package in &outdated {
+        let upgrade_to = package.upgrade_to.map_or(String::from("-"), |version| version.to_string());
+        let lock = package.lock.clone().unwrap_or_default();
+        table.add_row(row![package.name, package.installed, package.newest, upgrade_to, lock]);
+    }
+    table.printstd();
+    Ok(())
+}
 
 pub fn run(
     explicit: bool, deps: bool, orphans: bool, outdated: bool,
+    json: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    println!("List command not yet implemented");
+    if outdated {
+        return list_outdated(json, config);
+    }
+    
+    let local = LocalDatabase::load(&config.general.db_path)?;
+    let packages: Vec<&InstalledPackage> = local.packages.iter()
+        .filter(|package| !explicit || package.explicit)
+        .filter(|package| !deps || !package.explicit)
+        .filter(|package| !orphans || is_orphan(package, &local))
+        .collect();
+    if json {
+        let listed = packages.iter()
+            .map(|package| Listed {
+                name: &package.entry.name,
+                version: package.entry.version.to_string(),
+                repository: &package.repository,
+                explicit: package.explicit,
+                install_date: package.install_date,
+            })
+            .collect();
+        return print_json(&Packages::<Listed> { packages: listed });
+    }
+    if packages.is_empty() {
+        println!("{} No packages", "::".yellow().bold());
+        return Ok(());
+    }
+    
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Version", b->"Repository", b->"Reason"]);
+    for package in packages {
+        let reason = if package.explicit { "explicit" } else { "dependency" };
+        table.add_row(row![package.entry.name, package.entry.version, package.repository, reason]);
+    }
+    table.printstd();
     Ok(())
-}
+}
\ No newline at end of file
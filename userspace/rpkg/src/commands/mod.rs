@@ -8,6 +8,11 @@ pub mod update;
 pub mod clean;
 pub mod verify;
 pub mod repo;
+pub mod repo_build;
+pub mod serve;
+pub mod key;
+pub mod pin;
+pub mod rollback;
 pub mod owns;
 pub mod provides;
 pub mod download;
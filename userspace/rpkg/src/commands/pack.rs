@@ -2,8 +2,8 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    directory: &str, spec_file: &str, output: Option<String>,
-    config: &Config,
+    _directory: &str, _spec_file: &str, _output: Option<String>,
+    _config: &Config,
 ) -> Result<(), Box<dyn Error>> {
     println!("Pack command not yet implemented");
     Ok(())
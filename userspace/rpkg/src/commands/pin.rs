@@ -0,0 +1,78 @@
+use colored::*;
+use prettytable::{Table, row};
+use std::error::Error;
+use crate::config::{save_config, Config};
+use crate::database::{Dependency, LocalDatabase};
+use crate::display::print_success;
+
+/// Pin a package to the versions a spec like `foo=1.2.*` allows
+pub fn add(spec: &str, config: &Config, config_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let dependency = Dependency::parse(spec)?;
+    if dependency.requirement.is_none() {
+        return Err(format!("'{}' has no version requirement, as in {}=1.2.*", spec, dependency.name).into());
+    }
+    // As it was written; `=1.2.*` would print as `=1.2`
+    let requirement = spec.trim()[dependency.name.len()..].trim();
+    
+    let mut config = config.clone();
+    config.locks.pin.insert(dependency.name.clone(), requirement.to_string());
+    save_config(&config, config_path)?;
+    print_success(&format!("{} pinned to {}", dependency.name, requirement));
+    Ok(())
+}
+
+pub fn remove(name: &str, config: &Config, config_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut config = config.clone();
+    if config.locks.pin.remove(name).is_none() {
+        return Err(format!("{} is not pinned", name).into());
+    }
+    save_config(&config, config_path)?;
+    print_success(&format!("{} unpinned", name));
+    Ok(())
+}
+
+pub fn list(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.locks.pin.is_empty() && config.locks.hold.is_empty() {
+        println!("{} No packages are pinned or held", "::".yellow().bold());
+        return Ok(());
+    }
+    
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Lock"]);
+    for name in &config.locks.hold {
+        table.add_row(row![name, "held"]);
+    }
+    for (name, requirement) in &config.locks.pin {
+        table.add_row(row![name, format!("pinned to {}", requirement)]);
+    }
+    table.printstd();
+    Ok(())
+}
+
+/// Keep installed packages at the versions installed
+pub fn hold(packages: Vec<String>, config: &Config, config_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let local = LocalDatabase::load(&config.general.db_path)?;
+    let mut config = config.clone();
+    for name in &packages {
+        let package = local.get(name).ok_or_else(|| format!("{} is not installed", name))?;
+        if !config.locks.hold.contains(name) {
+            config.locks.hold.push(name.clone());
+        }
+        println!("  {} {} held at {}", "•".green(), name.bold(), package.entry.version);
+    }
+    save_config(&config, config_path)?;
+    Ok(())
+}
+
+pub fn unhold(packages: Vec<String>, config: &Config, config_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut config = config.clone();
+    for name in &packages {
+        if !config.locks.hold.contains(name) {
+            return Err(format!("{} is not held", name).into());
+        }
+        config.locks.hold.retain(|held| held != name);
+        println!("  {} {} released", "•".green(), name.bold());
+    }
+    save_config(&config, config_path)?;
+    Ok(())
+}
\ No newline at end of file
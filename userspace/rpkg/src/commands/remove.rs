@@ -2,12 +2,12 @@ use std::error::Error;
 use crate::config::Config;
 
 pub fn run(
-    packages: Vec<String>,
-    cascade: bool,
-    keep_deps: bool,
-    purge: bool,
-    config: &Config,
-    yes: bool,
+    _packages: Vec<String>,
+    _cascade: bool,
+    _keep_deps: bool,
+    _purge: bool,
+    _config: &Config,
+    _yes: bool,
 ) -> Result<(), Box<dyn Error>> {
     println!("Remove command not yet implemented");
     Ok(())
@@ -0,0 +1,106 @@
+use colored::*;
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
+use crate::database::{Dependency, Index, IndexEntry, METADATA_FILE};
+use crate::display::{format_size, print_success, print_warning};
+use crate::signing::{signature_path, SecretKey};
+use crate::transaction::HOOK_DIR;
+use crate::utils::INDEX_FILE;
+
+// The suffix of the package archives in a repository
+const ARCHIVE_SUFFIX: &str = ".tar.gz";
+
+/// The entry a package archive carries, with the size it unpacks to
+fn describe(path: &Path) -> Result<IndexEntry, Box<dyn Error>> {
+    let mut archive = Archive::new(GzDecoder::new(File::open(path)?));
+    let mut metadata = None;
+    let mut installed_size = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let relative: PathBuf =
+            entry.path()?.components().filter(|component| matches!(component, Component::Normal(_))).collect();
+        if relative == Path::new(METADATA_FILE) {
+            let mut text = String::new();
+            entry.read_to_string(&mut text)?;
+            metadata = Some(serde_json::from_str::<IndexEntry>(&text).map_err(|e| format!("{}: {}", METADATA_FILE, e))?);
+        } else if !relative.starts_with(HOOK_DIR) {
+            installed_size += entry.header().size()?;
+        }
+    }
+
+    let mut entry = metadata.ok_or_else(|| format!("has no {}", METADATA_FILE))?;
+    for spec in entry.depends.iter().chain(&entry.conflicts).chain(&entry.replaces) {
+        Dependency::parse(spec)?;
+    }
+    entry.installed_size = installed_size;
+    Ok(entry)
+}
+
+fn remove_signature(path: &Path) -> Result<(), Box<dyn Error>> {
+    match fs::remove_file(signature_path(path)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Write the index of the packages in `dir`, signing it and each package
+/// with `key` if one is given
+pub fn run(dir: &str, key: Option<String>) -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(dir);
+    let key = key.map(|path| SecretKey::load(Path::new(&path))).transpose()?;
+
+    let mut archives: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    archives.retain(|path| path.is_file() && path.to_string_lossy().ends_with(ARCHIVE_SUFFIX));
+    archives.sort();
+    if archives.is_empty() {
+        return Err(format!("no packages ({} files) in {}", ARCHIVE_SUFFIX, dir.display()).into());
+    }
+
+    println!("{} Scanning {} package(s)...", "::".blue().bold(), archives.len());
+
+    let mut index = Index::default();
+    let mut seen = HashSet::new();
+    for path in &archives {
+        let filename = path.file_name().unwrap().to_string_lossy().into_owned();
+        let mut entry = describe(path).map_err(|e| format!("{}: {}", filename, e))?;
+        if !seen.insert((entry.name.clone(), entry.version)) {
+            return Err(format!("{}: {} is in the repository twice", filename, entry).into());
+        }
+
+        let data = fs::read(path)?;
+        entry.filename = filename;
+        entry.size = data.len() as u64;
+        entry.sha256 = Some(hex::encode(Sha256::digest(&data)));
+        match &key {
+            Some(key) => fs::write(signature_path(path), key.sign(&data, &entry.filename))?,
+            // A signature left from an earlier build would no longer match
+            None => remove_signature(path)?,
+        }
+        println!("  {} {} {}", "•".green(), entry.to_string().bold(), format_size(entry.size).dimmed());
+        index.packages.push(entry);
+    }
+
+    let text = serde_json::to_string_pretty(&index)?;
+    let path = dir.join(INDEX_FILE);
+    let temp = path.with_extension("json.new");
+    fs::write(&temp, &text)?;
+    fs::rename(&temp, &path)?;
+    match &key {
+        Some(key) => fs::write(signature_path(&path), key.sign(text.as_bytes(), INDEX_FILE))?,
+        None => {
+            remove_signature(&path)?;
+            print_warning("No key given; the index and packages are unsigned");
+        }
+    }
+
+    print_success(&format!("Wrote {} with {} package(s)", path.display(), index.packages.len()));
+    Ok(())
+}
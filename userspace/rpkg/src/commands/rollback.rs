@@ -0,0 +1,59 @@
+use chrono::{DateTime, Local};
+use colored::*;
+use prettytable::{Table, row};
+use std::error::Error;
+use crate::config::Config;
+use crate::display::print_success;
+use crate::transaction::{self, State};
+use crate::triggers;
+use crate::utils::confirm_action;
+
+pub fn run(txid: Option<String>, force: bool, config: &Config, yes: bool) -> Result<(), Box<dyn Error>> {
+    let Some(txid) = txid else {
+        return list(config);
+    };
+
+    let journal = transaction::list(config)?
+        .into_iter()
+        .find(|journal| journal.id == txid)
+        .ok_or_else(|| format!("no transaction {}", txid))?;
+    println!("{} Transaction {} ({}): {}",
+        "::".blue().bold(),
+        journal.id.bold(),
+        journal.operation,
+        journal.packages.join(" ")
+    );
+    if !yes && !confirm_action("Roll back this transaction?")? {
+        println!("{} Rollback cancelled", "::".yellow().bold());
+        return Ok(());
+    }
+
+    let journal = transaction::rollback(config, &txid, force)?;
+    triggers::run(config, &journal)?;
+    print_success(&format!("Transaction {} rolled back", journal.id));
+    Ok(())
+}
+
+fn list(config: &Config) -> Result<(), Box<dyn Error>> {
+    let journals = transaction::list(config)?;
+    if journals.is_empty() {
+        println!("{} No transactions", "::".yellow().bold());
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![b->"ID", b->"Time", b->"Operation", b->"Packages", b->"State"]);
+    for journal in journals.iter().rev() {
+        let time = DateTime::from_timestamp(journal.time, 0)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let state = match journal.state {
+            State::Pending => "pending",
+            State::Committed => "committed",
+            State::RolledBack => "rolled back",
+        };
+        table.add_row(row![journal.id, time, journal.operation, journal.packages.join(" "), state]);
+    }
+    table.printstd();
+    Ok(())
+}
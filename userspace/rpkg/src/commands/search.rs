@@ -1,13 +1,22 @@
 use colored::*;
-use prettytable::{Table, row, cell};
+use prettytable::{Table, row};
+use serde::Serialize;
 use std::error::Error;
 use crate::config::Config;
-use crate::utils::PackageManager;
+use crate::display::print_json;
+use crate::utils::{PackageManager, SearchResult};
+
+#[derive(Serialize)]
+struct Results<'a> {
+    query: &'a str,
+    packages: &'a [SearchResult],
+}
 
 pub fn run(
     query: &str,
     installed: bool,
     repo: Option<String>,
+    json: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
     let pm = PackageManager::new(config)?;
@@ -20,6 +29,10 @@ pub fn run(
         pm.search_all(query)?
     };
     
+    if json {
+        return print_json(&Results { query, packages: &results });
+    }
+    
     if results.is_empty() {
         println!("{} No packages found matching '{}'", 
             "::".yellow().bold(), 
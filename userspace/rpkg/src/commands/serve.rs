@@ -0,0 +1,75 @@
+use chrono::Local;
+use colored::*;
+use std::error::Error;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// Requests answered at once, so one slow download does not hold up the rest
+const WORKERS: usize = 4;
+
+/// The file a request is for, if it names one inside `dir`
+fn file_for(dir: &Path, url: &str) -> Option<PathBuf> {
+    let path = Path::new(url.split(['?', '#']).next()?.trim_start_matches('/'));
+    if path.components().any(|component| !matches!(component, Component::Normal(_))) {
+        return None;
+    }
+    let path = dir.join(path);
+    path.is_file().then_some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => "application/json",
+        Some("gz") => "application/gzip",
+        Some("minisig") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn answer(dir: &Path, request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let client = request.remote_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+
+    let file = file_for(dir, &url).and_then(|path| File::open(&path).ok().map(|file| (path, file)));
+    let (status, result) = match file {
+        _ if !matches!(method, Method::Get | Method::Head) => (405, request.respond(Response::empty(405))),
+        Some((path, file)) => {
+            let header = Header::from_bytes("Content-Type", content_type(&path)).unwrap();
+            (200, request.respond(Response::from_file(file).with_header(header)))
+        }
+        None => (404, request.respond(Response::empty(404))),
+    };
+
+    let status = match status {
+        200 => status.to_string().green(),
+        _ => status.to_string().yellow(),
+    };
+    let failed = match result {
+        Ok(()) => String::new(),
+        Err(e) => format!(" ({})", e).red().to_string(),
+    };
+    println!("{} {} {} {} {}{}", Local::now().format("%H:%M:%S").to_string().dimmed(), client, method, url, status, failed);
+}
+
+/// Serve a repository directory, as `rpkg repo-build` leaves it, over HTTP
+pub fn run(dir: &str, bind: &str) -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(dir).canonicalize()?;
+    let server = Server::http(bind).map_err(|e| format!("cannot listen on {}: {}", bind, e))?;
+
+    println!("{} Serving {} on http://{}", "::".blue().bold(), dir.display(), bind);
+    println!("{} Add it elsewhere with 'rpkg repo add <name> http://<this host>:<port>'", "::".blue().bold());
+
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                while let Ok(request) = server.recv() {
+                    answer(&dir, request);
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -1,10 +1,143 @@
+use chrono::{DateTime, Local};
+use colored::*;
+use prettytable::{Table, row};
+use serde::Serialize;
 use std::error::Error;
+use std::fs;
+use std::time::UNIX_EPOCH;
 use crate::config::Config;
+use crate::database::{Index, LocalDatabase};
+use crate::display::{format_size, print_json};
+use crate::transaction;
+use crate::utils::PackageManager;
 
-pub fn run(
+#[derive(Serialize)]
+struct PackageStats {
+    installed: usize,
+    explicit: usize,
+    dependencies: usize,
+    installed_size: u64,
+}
+
+#[derive(Serialize)]
+struct RepositoryStats {
+    name: String,
+    enabled: bool,
+    /// In its index, if it has been synchronized
+    packages: Option<usize>,
+    /// When, in seconds since the epoch
+    synchronized: Option<i64>,
+}
 
+#[derive(Serialize)]
+struct CacheStats {
+    dir: String,
+    archives: usize,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct TransactionStats {
+    count: usize,
+    last: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    packages: PackageStats,
+    repositories: Vec<RepositoryStats>,
+    cache: CacheStats,
+    transactions: TransactionStats,
+}
+
+fn date(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+pub fn run(
+    json: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Stats command not yet implemented");
+    let pm = PackageManager::new(config)?;
+    let local = LocalDatabase::load(&config.general.db_path)?;
+    
+    let explicit = local.packages.iter().filter(|package| package.explicit).count();
+    let packages = PackageStats {
+        installed: local.packages.len(),
+        explicit,
+        dependencies: local.packages.len() - explicit,
+        installed_size: local.packages.iter().map(|package| package.entry.installed_size).sum(),
+    };
+    
+    let mut repositories = Vec::new();
+    for repo in &config.repositories {
+        let path = pm.sync_path(&repo.name);
+        let (packages, synchronized) = match fs::metadata(&path) {
+            Ok(metadata) => {
+                let time = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as i64);
+                (Some(Index::load(&path)?.packages.len()), Some(time))
+            }
+            Err(_) => (None, None),
+        };
+        repositories.push(RepositoryStats { name: repo.name.clone(), enabled: repo.enabled, packages, synchronized });
+    }
+    
+    let mut cache = CacheStats { dir: config.cache.dir.clone(), archives: 0, size: 0 };
+    if let Ok(entries) = fs::read_dir(&config.cache.dir) {
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            cache.size += metadata.len();
+            if entry.file_name().to_string_lossy().ends_with(".tar.gz") {
+                cache.archives += 1;
+            }
+        }
+    }
+    
+    let journals = transaction::list(config)?;
+    let transactions = TransactionStats {
+        count: journals.len(),
+        last: journals.last().map(|journal| journal.id.clone()),
+    };
+    
+    let stats = Stats { packages, repositories, cache, transactions };
+    if json {
+        return print_json(&stats);
+    }
+    
+    println!("{}", "Packages".bold());
+    println!("  Installed:       {} ({} explicit, {} as dependencies)",
+        stats.packages.installed,
+        stats.packages.explicit,
+        stats.packages.dependencies
+    );
+    println!("  Installed size:  {}", format_size(stats.packages.installed_size));
+    
+    println!("\n{}", "Repositories".bold());
+    let mut table = Table::new();
+    table.add_row(row![b->"Name", b->"Enabled", b->"Packages", b->"Synchronized"]);
+    for repo in &stats.repositories {
+        let enabled = if repo.enabled { "yes" } else { "no" };
+        let packages = repo.packages.map_or(String::from("-"), |count| count.to_string());
+        let synchronized = repo.synchronized.map_or(String::from("never"), date);
+        table.add_row(row![repo.name, enabled, packages, synchronized]);
+    }
+    table.printstd();
+    
+    println!("\n{}", "Cache".bold());
+    println!("  Directory:       {}", stats.cache.dir);
+    println!("  Archives:        {} ({})", stats.cache.archives, format_size(stats.cache.size));
+    
+    println!("\n{}", "Transactions".bold());
+    println!("  Recorded:        {}", stats.transactions.count);
+    if let Some(last) = &stats.transactions.last {
+        println!("  Last:            {}", last);
+    }
+    
     Ok(())
 }
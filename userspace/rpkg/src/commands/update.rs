@@ -1,10 +1,37 @@
+use colored::*;
 use std::error::Error;
 use crate::config::Config;
+use crate::exit::Exit;
+use crate::utils::PackageManager;
 
 pub fn run(
     force: bool,
+    allow_unsigned: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Update command not yet implemented");
+    let pm = PackageManager::new(config)?;
+    
+    println!("{} Synchronizing package databases...", "::".blue().bold());
+    
+    let repos: Vec<_> = config.repositories.iter().filter(|repo| repo.enabled).collect();
+    let mut failed = 0;
+    for repo in &repos {
+        match pm.sync_repository(repo, force, allow_unsigned) {
+            Ok(true) => println!("  {} {} updated", "•".green(), repo.name.bold()),
+            Ok(false) => println!("  {} {} is up to date", "•".dimmed(), repo.name.bold()),
+            // One repository being down should not keep the others stale
+            Err(e) => {
+                println!("  {} {}: {}", "•".red(), repo.name.bold(), e);
+                failed += 1;
+            }
+        }
+    }
+    
+    if failed > 0 && failed == repos.len() {
+        return Err("no repository could be synchronized".into());
+    }
+    if failed > 0 {
+        return Err(Exit::Partial(format!("{} of {} repositories could not be synchronized", failed, repos.len())).into());
+    }
     Ok(())
 }
@@ -1,11 +1,35 @@
+use colored::*;
 use std::error::Error;
+use crate::commands::install::{execute, Options};
 use crate::config::Config;
+use crate::exit::Exit;
+use crate::utils::PackageManager;
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     packages: Vec<String>, ignore: Vec<String>, download_only: bool,
+    allow_unsigned: bool,
+    explain: bool,
     config: &Config,
     yes: bool,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Upgrade command not yet implemented");
-    Ok(())
-}
+    let pm = PackageManager::new(config)?;
+    
+    println!("{} Resolving upgrades...", "::".blue().bold());
+    
+    let resolution = pm.resolve_upgrade(&packages, &ignore)?;
+    if resolution.conflicts.is_empty() && resolution.to_upgrade.is_empty() && resolution.to_install.is_empty()
+        && resolution.to_remove.is_empty()
+    {
+        return Err(Exit::NothingToDo(String::from("Nothing to upgrade")).into());
+    }
+    
+    let options = Options {
+        operation: "upgrade",
+        as_deps: false,
+        allow_unsigned,
+        download_only,
+        explain,
+    };
+    execute(&pm, &resolution, &options, config, yes)
+}
\ No newline at end of file
@@ -1,10 +1,378 @@
+use colored::*;
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tar::Archive;
 use crate::config::Config;
+use crate::database::{FileKind, FileRecord, InstalledPackage, LocalDatabase};
+use crate::display::print_json;
+use crate::exit::Exit;
+use crate::transaction::sha256;
+use crate::utils::{PackageInfo, PackageManager};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Damage {
+    Missing,
+    /// Something of another kind is in its place
+    Kind,
+    Modified,
+    Mode,
+    /// Only the modification time differs, which is not damage
+    Mtime,
+}
+
+impl Damage {
+    fn describe(self) -> &'static str {
+        match self {
+            Damage::Missing => "missing",
+            Damage::Kind => "replaced by another kind of file",
+            Damage::Modified => "modified",
+            Damage::Mode => "permissions changed",
+            Damage::Mtime => "touched",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Finding {
+    path: String,
+    damage: Damage,
+    /// Whether --repair put it right
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repaired: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct PackageReport {
+    name: String,
+    version: String,
+    files: usize,
+    findings: Vec<Finding>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    packages: Vec<PackageReport>,
+    /// Files still damaged
+    damaged: usize,
+    repaired: usize,
+}
+
+fn check(root: &Path, record: &FileRecord) -> Option<Damage> {
+    if fs::symlink_metadata(root.join(&record.path)).is_err() {
+        return Some(Damage::Missing);
+    }
+    let Ok(current) = FileRecord::read(root, &record.path) else {
+        return Some(Damage::Modified);
+    };
+    if current.kind != record.kind {
+        Some(Damage::Kind)
+    } else if current.sha256 != record.sha256 {
+        Some(Damage::Modified)
+    } else if current.mode != record.mode && record.kind != FileKind::Symlink {
+        Some(Damage::Mode)
+    } else if current.mtime != record.mtime && record.kind != FileKind::Directory {
+        // A directory's time changes whenever anything is added to it
+        Some(Damage::Mtime)
+    } else {
+        None
+    }
+}
+
+// An archive entry's path, as the file lists have it
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|component| matches!(component, Component::Normal(_))).collect()
+}
+
+/// Put damaged files back from the package's archive, fetching it if it is
+/// no longer cached. A file is only put back if it comes out of the archive
+/// as it was installed. Returns the paths put right.
+fn repair(
+    pm: &PackageManager,
+    package: &InstalledPackage,
+    records: &[&FileRecord],
+    config: &Config,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let root = Path::new(&config.general.root_dir);
+    let mut repaired = Vec::new();
+
+    let mut extract = HashMap::new();
+    for record in records {
+        if check(root, record) == Some(Damage::Mode) {
+            fs::set_permissions(root.join(&record.path), fs::Permissions::from_mode(record.mode))?;
+            repaired.push(record.path.clone());
+        } else {
+            extract.insert(record.path.clone(), *record);
+        }
+    }
+    if extract.is_empty() {
+        return Ok(repaired);
+    }
+
+    let mut path = Path::new(&config.cache.dir).join(&package.entry.filename);
+    if !path.exists() {
+        path = pm.download_package(&PackageInfo::from(package), false)?;
+    }
+    let mut archive = Archive::new(GzDecoder::new(File::open(&path)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let relative = normalize(&entry.path()?);
+        let Some(record) = extract.remove(&relative) else {
+            continue;
+        };
+        let target = root.join(&relative);
+        if record.kind == FileKind::Directory {
+            fs::create_dir_all(&target)?;
+            fs::set_permissions(&target, fs::Permissions::from_mode(record.mode))?;
+            repaired.push(relative);
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let name = target.file_name().ok_or("no file name")?.to_string_lossy();
+        let temp = target.with_file_name(format!(".{}.rpkg-repair", name));
+        let _ = fs::remove_file(&temp);
+        entry.unpack(&temp)?;
+        if record.kind == FileKind::File {
+            fs::set_permissions(&temp, fs::Permissions::from_mode(record.mode))?;
+        }
+        // A directory in the file's place is left for the administrator
+        if sha256(&temp).ok() == record.sha256 && fs::rename(&temp, &target).is_ok() {
+            repaired.push(relative);
+        } else {
+            let _ = fs::remove_file(&temp);
+        }
+    }
+    Ok(repaired)
+}
 
 pub fn run(
     packages: Vec<String>, all: bool, quiet: bool,
+    repair_files: bool,
+    json: bool,
     config: &Config,
 ) -> Result<(), Box<dyn Error>> {
-    println!("Verify command not yet implemented");
+    let pm = PackageManager::new(config)?;
+    let local = LocalDatabase::load(&config.general.db_path)?;
+    let root = Path::new(&config.general.root_dir);
+
+    let selected: Vec<&InstalledPackage> = if all {
+        local.packages.iter().collect()
+    } else if packages.is_empty() {
+        return Err("name the packages to verify, or use --all".into());
+    } else {
+        packages.iter()
+            .map(|name| local.get(name).ok_or_else(|| format!("{} is not installed", name)))
+            .collect::<Result<_, _>>()?
+    };
+
+    if !json && !quiet {
+        println!("{} Checking {} package(s)...", "::".blue().bold(), selected.len());
+    }
+
+    let mut report = Report { packages: Vec::new(), damaged: 0, repaired: 0 };
+    for package in selected {
+        let mut records = local.files(&package.entry.name)?;
+        let mut findings: Vec<Finding> = records.iter()
+            .filter_map(|record| check(root, record).map(|damage| Finding {
+                path: format!("/{}", record.path.display()),
+                damage,
+                repaired: None,
+            }))
+            .collect();
+
+        let damaged: Vec<&FileRecord> = records.iter()
+            .filter(|record| matches!(check(root, record), Some(damage) if damage != Damage::Mtime))
+            .collect();
+        if repair_files && !damaged.is_empty() {
+            let repaired = repair(&pm, package, &damaged, config)
+                .map_err(|e| format!("cannot repair {}: {}", package.entry.name, e))?;
+            for finding in findings.iter_mut().filter(|finding| finding.damage != Damage::Mtime) {
+                finding.repaired = Some(repaired.iter().any(|path| finding.path[1..] == *path.to_string_lossy()));
+            }
+            // The repaired files' new times are the ones to check against
+            for record in records.iter_mut().filter(|record| repaired.contains(&record.path)) {
+                *record = FileRecord::read(root, &record.path)?;
+            }
+            local.set_files(&package.entry.name, &records)?;
+        }
+
+        for finding in &findings {
+            match finding.repaired {
+                Some(true) => report.repaired += 1,
+                _ if finding.damage != Damage::Mtime => report.damaged += 1,
+                _ => {}
+            }
+        }
+        report.packages.push(PackageReport {
+            name: package.entry.name.clone(),
+            version: package.entry.version.to_string(),
+            files: records.len(),
+            findings,
+        });
+    }
+
+    if json {
+        print_json(&report)?;
+    } else {
+        for package in &report.packages {
+            if package.files == 0 {
+                if !quiet {
+                    println!("  {} {}-{}: no file list", "•".yellow(), package.name.bold(), package.version);
+                }
+                continue;
+            }
+            if package.findings.is_empty() {
+                if !quiet {
+                    println!("  {} {}-{}: {} files intact", "•".green(), package.name.bold(), package.version, package.files);
+                }
+                continue;
+            }
+            println!("  {} {}-{}:", "•".red(), package.name.bold(), package.version);
+            for finding in &package.findings {
+                let status = match finding.repaired {
+                    Some(true) => " (repaired)".green().to_string(),
+                    Some(false) => " (not repaired)".red().to_string(),
+                    None => String::new(),
+                };
+                println!("      {} {}{}", finding.path, finding.damage.describe().dimmed(), status);
+            }
+        }
+    }
+
+    if report.damaged > 0 && report.repaired > 0 {
+        return Err(Exit::Partial(format!("{} file(s) repaired, {} still damaged", report.repaired, report.damaged)).into());
+    }
+    if report.damaged > 0 {
+        let hint = if repair_files { "" } else { "; use --repair to restore them" };
+        return Err(format!("{} damaged file(s){}", report.damaged, hint).into());
+    }
+    if !json && !quiet {
+        println!("{} All files intact", "✓".green().bold());
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    const CONFIG: &[u8] = b"verbose = false\n";
+    const BINARY: &[u8] = b"\x7fELF";
+
+    // Put `contents` at `path` under `root`, and describe it as installed
+    fn install(root: &Path, path: &str, contents: &[u8], mode: u32) -> FileRecord {
+        let full = root.join(path);
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        fs::write(&full, contents).unwrap();
+        fs::set_permissions(&full, fs::Permissions::from_mode(mode)).unwrap();
+        FileRecord::read(root, Path::new(path)).unwrap()
+    }
+
+    fn archive(path: &Path, files: &[(&str, &[u8], u32)]) {
+        let mut builder = tar::Builder::new(GzEncoder::new(File::create(path).unwrap(), Compression::default()));
+        for (name, contents, mode) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            builder.append_data(&mut header, format!("./{}", name), *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    // A root and a cache to repair app-1.0.0 in
+    fn setup() -> (TempDir, TempDir, Config, InstalledPackage) {
+        let root = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.general.root_dir = root.path().to_string_lossy().into_owned();
+        config.cache.dir = cache.path().to_string_lossy().into_owned();
+        let package = serde_json::from_value(serde_json::json!({
+            "name": "app",
+            "version": "1.0.0",
+            "filename": "app-1.0.0.rpkg",
+            "repository": "main",
+            "explicit": true,
+            "install_date": 0,
+        }))
+        .unwrap();
+        (root, cache, config, package)
+    }
+
+    #[test]
+    fn finds_each_kind_of_damage() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let record = install(root, "etc/app.conf", CONFIG, 0o644);
+        let full = root.join("etc/app.conf");
+        assert_eq!(check(root, &record), None);
+
+        File::options().write(true).open(&full).unwrap().set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_eq!(check(root, &record), Some(Damage::Mtime));
+
+        fs::set_permissions(&full, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(check(root, &record), Some(Damage::Mode));
+
+        fs::write(&full, b"verbose = true\n").unwrap();
+        assert_eq!(check(root, &record), Some(Damage::Modified));
+
+        fs::remove_file(&full).unwrap();
+        assert_eq!(check(root, &record), Some(Damage::Missing));
+
+        fs::create_dir(&full).unwrap();
+        assert_eq!(check(root, &record), Some(Damage::Kind));
+    }
+
+    #[test]
+    fn normalizes_archive_paths() {
+        assert_eq!(normalize(Path::new("./usr/bin/app")), PathBuf::from("usr/bin/app"));
+        assert_eq!(normalize(Path::new("/etc/app.conf")), PathBuf::from("etc/app.conf"));
+    }
+
+    #[test]
+    fn repairs_from_the_cached_archive() {
+        let (root, cache, config, package) = setup();
+        let pm = PackageManager::new(&config).unwrap();
+        archive(
+            &cache.path().join("app-1.0.0.rpkg"),
+            &[("usr/bin/app", BINARY, 0o755), ("etc/app.conf", CONFIG, 0o644)],
+        );
+
+        let binary = install(root.path(), "usr/bin/app", BINARY, 0o755);
+        let config_file = install(root.path(), "etc/app.conf", CONFIG, 0o644);
+        fs::set_permissions(root.path().join("usr/bin/app"), fs::Permissions::from_mode(0o777)).unwrap();
+        fs::write(root.path().join("etc/app.conf"), b"damaged").unwrap();
+
+        let mut repaired = repair(&pm, &package, &[&binary, &config_file], &config).unwrap();
+        repaired.sort();
+        assert_eq!(repaired, [PathBuf::from("etc/app.conf"), PathBuf::from("usr/bin/app")]);
+        assert_eq!(fs::read(root.path().join("etc/app.conf")).unwrap(), CONFIG);
+        assert_eq!(check(root.path(), &binary), None);
+        assert!(!root.path().join("etc/.app.conf.rpkg-repair").exists());
+    }
+
+    #[test]
+    fn leaves_a_file_the_archive_does_not_match() {
+        let (root, cache, config, package) = setup();
+        let pm = PackageManager::new(&config).unwrap();
+        archive(&cache.path().join("app-1.0.0.rpkg"), &[("etc/app.conf", b"something else", 0o644)]);
+
+        let record = install(root.path(), "etc/app.conf", CONFIG, 0o644);
+        fs::write(root.path().join("etc/app.conf"), b"damaged").unwrap();
+        assert!(repair(&pm, &package, &[&record], &config).unwrap().is_empty());
+        assert_eq!(fs::read(root.path().join("etc/app.conf")).unwrap(), b"damaged");
+        assert!(!root.path().join("etc/.app.conf.rpkg-repair").exists());
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
@@ -10,6 +11,10 @@ pub struct Config {
     pub cache: CacheConfig,
     pub network: NetworkConfig,
     pub security: SecurityConfig,
+    #[serde(default)]
+    pub locks: LocksConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +55,52 @@ pub struct SecurityConfig {
     pub verify_signatures: bool,
     pub verify_checksums: bool,
     pub allow_downgrade: bool,
+    /// Minisign public keys trusted to sign indexes and packages
+    #[serde(default = "default_keyring_dir")]
+    pub keyring_dir: String,
+}
+
+/// Packages kept back from upgrades, which the resolver never changes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LocksConfig {
+    /// Installed packages kept at the version installed
+    #[serde(default)]
+    pub hold: Vec<String>,
+    /// Packages kept to the versions a requirement allows, as in `=1.2.*`
+    #[serde(default)]
+    pub pin: BTreeMap<String, String>,
+}
+
+/// How packages' hooks and triggers are run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Run them in a temporary root, cut off from the network and the
+    /// caller's environment, with the limits below
+    pub sandbox: bool,
+    /// Seconds of CPU time
+    pub cpu_seconds: u64,
+    /// Of address space, in MiB
+    pub memory_mb: u64,
+    pub open_files: u64,
+    /// Seconds one may run before it is killed
+    pub timeout_seconds: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            sandbox: true,
+            cpu_seconds: 300,
+            memory_mb: 2048,
+            open_files: 1024,
+            timeout_seconds: 600,
+        }
+    }
+}
+
+fn default_keyring_dir() -> String {
+    String::from("/etc/rpkg/keys")
 }
 
 impl Default for Config {
@@ -92,7 +143,10 @@ impl Default for Config {
                 verify_signatures: true,
                 verify_checksums: true,
                 allow_downgrade: false,
+                keyring_dir: default_keyring_dir(),
             },
+            locks: LocksConfig::default(),
+            hooks: HooksConfig::default(),
         }
     }
 }
@@ -0,0 +1,288 @@
+//! Package metadata: the index each repository publishes, which `rpkg
+//! update` keeps a copy of, and the local database of what is installed
+//!
+//! An index is a JSON object whose `packages` list has an entry per version
+//! of each package. Dependencies, conflicts and replaces are a package name
+//! with an optional Cargo-style version requirement, as in `libfoo` or
+//! `libfoo>=1.2, <2`. A package provides its own name, and whatever its
+//! `provides` lists: a name, or a name and version as in `libfoo=1.4.0`. A
+//! dependency with a version requirement is only met by a provided name that
+//! has a version.
+//!
+//! Beside the local database, each installed package has a list of the files
+//! it installed, as they were once in place: the hash of each file's
+//! contents or symlink's target, and its mode and modification time. `rpkg
+//! verify` checks the system against these.
+//!
+//! A package archive describes itself in `.rpkg/package.json`, an index
+//! entry without the fields that are about the archive, which `rpkg
+//! repo-build` fills in.
+
+use semver::VersionReq;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::transaction::sha256;
+use crate::utils::Version;
+
+// Where the local database is, under the database directory
+const LOCAL_FILE: &str = "installed.json";
+// and the directory of the packages' file lists
+const FILES_DIR: &str = "files";
+/// A package's own entry, inside its archive
+pub const METADATA_FILE: &str = ".rpkg/package.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OptionalDependency {
+    pub name: String,
+    /// What it adds
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub name: String,
+    pub version: Version,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default)]
+    pub optional: Vec<OptionalDependency>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Packages this one takes the place of; they are removed when it is
+    /// installed
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    /// The archive's name in the repository
+    #[serde(default)]
+    pub filename: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub installed_size: u64,
+    /// Of the archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl IndexEntry {
+    /// The names it provides besides its own, with their versions
+    pub fn provided(&self) -> impl Iterator<Item = (&str, Option<Version>)> {
+        self.provides.iter().map(|provide| match provide.split_once('=') {
+            Some((name, version)) => (name.trim(), Version::parse(version.trim())),
+            None => (provide.trim(), None),
+        })
+    }
+}
+
+impl fmt::Display for IndexEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.name, self.version)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub packages: Vec<IndexEntry>,
+}
+
+impl Index {
+    pub fn load(path: &Path) -> Result<Index, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+}
+
+/// A package name and the versions of it that will do
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub name: String,
+    pub requirement: Option<VersionReq>,
+}
+
+impl Dependency {
+    pub fn parse(spec: &str) -> Result<Dependency, Box<dyn Error>> {
+        let spec = spec.trim();
+        let split = spec.find(|c| "<>=~^".contains(c)).unwrap_or(spec.len());
+        let (name, requirement) = spec.split_at(split);
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("'{}' names no package", spec).into());
+        }
+        let requirement = match requirement.trim() {
+            "" => None,
+            requirement => Some(
+                VersionReq::parse(requirement).map_err(|e| format!("bad version requirement in '{}': {}", spec, e))?,
+            ),
+        };
+        Ok(Dependency { name: name.to_string(), requirement })
+    }
+
+    fn allows(&self, version: &Version) -> bool {
+        self.requirement.as_ref().is_none_or(|requirement| requirement.matches(&version.to_semver()))
+    }
+
+    /// Whether a package meets this by its name or by one it provides
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        if entry.name == self.name {
+            return self.allows(&entry.version);
+        }
+        entry.provided().any(|(name, version)| {
+            name == self.name
+                && match (&self.requirement, version) {
+                    (None, _) => true,
+                    (Some(_), Some(version)) => self.allows(&version),
+                    (Some(_), None) => false,
+                }
+        })
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.requirement {
+            Some(requirement) => write!(f, "{}{}", self.name, requirement),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledPackage {
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+    /// The repository it was installed from
+    pub repository: String,
+    /// Asked for by name, rather than pulled in as a dependency
+    pub explicit: bool,
+    /// When it was installed, in seconds since the epoch
+    pub install_date: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Symlink,
+    Directory,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRecord {
+    /// Relative to the root
+    pub path: PathBuf,
+    pub kind: FileKind,
+    /// Of the contents, or of the symlink's target; none for a directory
+    pub sha256: Option<String>,
+    /// The permission bits
+    pub mode: u32,
+    /// In seconds since the epoch
+    pub mtime: i64,
+}
+
+impl FileRecord {
+    /// Describe `path` under `root` as it is now
+    pub fn read(root: &Path, path: &Path) -> Result<FileRecord, Box<dyn Error>> {
+        let full = root.join(path);
+        let metadata = fs::symlink_metadata(&full)?;
+        let kind = if metadata.file_type().is_symlink() {
+            FileKind::Symlink
+        } else if metadata.is_dir() {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        };
+        let sha256 = match kind {
+            FileKind::Directory => None,
+            _ => Some(sha256(&full)?),
+        };
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs() as i64);
+        Ok(FileRecord {
+            path: path.to_path_buf(),
+            kind,
+            sha256,
+            mode: metadata.permissions().mode() & 0o7777,
+            mtime,
+        })
+    }
+}
+
+pub struct LocalDatabase {
+    path: PathBuf,
+    pub packages: Vec<InstalledPackage>,
+}
+
+impl LocalDatabase {
+    pub fn load(db_path: &str) -> Result<LocalDatabase, Box<dyn Error>> {
+        let path = Path::new(db_path).join(LOCAL_FILE);
+        let packages = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(LocalDatabase { path, packages })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InstalledPackage> {
+        self.packages.iter().find(|package| package.entry.name == name)
+    }
+
+    /// Record a package as installed, in place of any other version of it
+    pub fn record(&mut self, package: InstalledPackage) {
+        self.remove(&package.entry.name);
+        self.packages.push(package);
+        self.packages.sort_by(|a, b| a.entry.name.cmp(&b.entry.name));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<InstalledPackage> {
+        let position = self.packages.iter().position(|package| package.entry.name == name)?;
+        Some(self.packages.remove(position))
+    }
+
+    fn files_path(&self, name: &str) -> PathBuf {
+        self.path.with_file_name(FILES_DIR).join(format!("{}.json", name))
+    }
+
+    /// The files an installed package put in place
+    pub fn files(&self, name: &str) -> Result<Vec<FileRecord>, Box<dyn Error>> {
+        let path = self.files_path(name);
+        match fs::read_to_string(&path) {
+            Ok(text) => Ok(serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_files(&self, name: &str, files: &[FileRecord]) -> Result<(), Box<dyn Error>> {
+        let path = self.files_path(name);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let temp = path.with_extension("json.new");
+        fs::write(&temp, serde_json::to_string_pretty(files)?)?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    pub fn remove_files(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        match fs::remove_file(self.files_path(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(self.path.parent().unwrap())?;
+        let temp = self.path.with_extension("json.new");
+        fs::write(&temp, serde_json::to_string_pretty(&self.packages)?)?;
+        fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+}
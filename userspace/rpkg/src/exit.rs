@@ -0,0 +1,65 @@
+//! Exit codes, so that scripts can tell outcomes apart without reading
+//! what rpkg prints
+//!
+//! | Code | Meaning                                                     |
+//! |------|-------------------------------------------------------------|
+//! | 0    | Success                                                     |
+//! | 1    | Failure                                                     |
+//! | 2    | Bad command line                                            |
+//! | 3    | Nothing to do: everything asked for was already so          |
+//! | 4    | Partial failure: some of what was asked for was done        |
+//! | 5    | Dependency conflict: no set of packages meets the request   |
+//!
+//! A command ends with one of the last three by returning an [`Exit`] as
+//! its error.
+
+use std::error::Error;
+use std::fmt;
+
+pub const FAILURE: i32 = 1;
+// 2 is clap's, for a bad command line
+pub const NOTHING_TO_DO: i32 = 3;
+pub const PARTIAL: i32 = 4;
+pub const CONFLICT: i32 = 5;
+
+#[derive(Debug)]
+pub enum Exit {
+    NothingToDo(String),
+    Partial(String),
+    Conflict(String),
+}
+
+impl Exit {
+    pub fn code(&self) -> i32 {
+        match self {
+            Exit::NothingToDo(_) => NOTHING_TO_DO,
+            Exit::Partial(_) => PARTIAL,
+            Exit::Conflict(_) => CONFLICT,
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Exit::NothingToDo(message) | Exit::Partial(message) | Exit::Conflict(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for Exit {}
+
+/// The code to exit with after a command failed with `error`
+pub fn code(error: &(dyn Error + 'static)) -> i32 {
+    error.downcast_ref::<Exit>().map_or(FAILURE, Exit::code)
+}
+
+/// What `code` means, as the JSON output names it
+pub fn kind(code: i32) -> &'static str {
+    match code {
+        NOTHING_TO_DO => "nothing-to-do",
+        PARTIAL => "partial",
+        CONFLICT => "conflict",
+        _ => "failure",
+    }
+}
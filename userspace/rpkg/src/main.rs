@@ -1,17 +1,32 @@
 use clap::{Parser, Subcommand};
 use colored::*;
+use std::error::Error;
 use std::process;
 
 mod commands;
 mod config;
+mod database;
 mod utils;
 mod display;
+mod exit;
+mod resolver;
+mod sandbox;
+mod signing;
+mod transaction;
+mod triggers;
 
 #[derive(Parser)]
 #[command(name = "rpkg")]
 #[command(author = "RustOS Contributors")]
 #[command(version = "1.0.0")]
 #[command(about = "Package manager for RustOS", long_about = None)]
+#[command(after_help = "Exit codes:
+  0  success
+  1  failure
+  2  bad command line
+  3  nothing to do
+  4  partial failure
+  5  dependency conflict")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -28,6 +43,9 @@ struct Cli {
     #[arg(long, global = true)]
     no_color: bool,
 
+    #[arg(long, global = true, help = "Print results, and errors, as JSON (search, info, list, verify, stats)")]
+    json: bool,
+
     #[arg(long, value_name = "FILE", global = true)]
     config: Option<String>,
 }
@@ -47,6 +65,12 @@ enum Commands {
 
         #[arg(long)]
         reinstall: bool,
+
+        #[arg(long, help = "Install packages that have no signature")]
+        allow_unsigned: bool,
+
+        #[arg(long, help = "Show why each package was chosen")]
+        explain: bool,
     },
 
     #[command(about = "Remove one or more packages")]
@@ -73,6 +97,12 @@ enum Commands {
 
         #[arg(long)]
         download_only: bool,
+
+        #[arg(long, help = "Install packages that have no signature")]
+        allow_unsigned: bool,
+
+        #[arg(long, help = "Show why each package was chosen")]
+        explain: bool,
     },
 
     #[command(about = "Search for packages")]
@@ -113,7 +143,7 @@ enum Commands {
         #[arg(short, long)]
         orphans: bool,
 
-        #[arg(short, long)]
+        #[arg(short = 'u', long)]
         outdated: bool,
     },
 
@@ -121,6 +151,9 @@ enum Commands {
     Update {
         #[arg(long)]
         force: bool,
+
+        #[arg(long, help = "Accept repository indexes that have no signature")]
+        allow_unsigned: bool,
     },
 
     #[command(about = "Clean package cache")]
@@ -141,6 +174,9 @@ enum Commands {
 
         #[arg(long)]
         quiet: bool,
+
+        #[arg(long, help = "Restore damaged files from the package archives")]
+        repair: bool,
     },
 
     #[command(about = "Manage repositories")]
@@ -149,6 +185,56 @@ enum Commands {
         action: RepoAction,
     },
 
+    #[command(about = "Write a repository's index for the packages in a directory")]
+    RepoBuild {
+        #[arg(required = true)]
+        dir: String,
+
+        #[arg(short, long, value_name = "FILE", help = "Sign the index and packages with this secret key")]
+        key: Option<String>,
+    },
+
+    #[command(about = "Serve a repository directory over HTTP")]
+    Serve {
+        #[arg(required = true)]
+        dir: String,
+
+        #[arg(short, long, default_value = "0.0.0.0:8080")]
+        bind: String,
+    },
+
+    #[command(about = "Manage keys trusted to sign repositories and packages")]
+    Key {
+        #[command(subcommand)]
+        action: KeyAction,
+    },
+
+    #[command(about = "Pin packages to versions")]
+    Pin {
+        #[command(subcommand)]
+        action: PinAction,
+    },
+
+    #[command(about = "Hold installed packages back from upgrades")]
+    Hold {
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    #[command(about = "Let held packages be upgraded again")]
+    Unhold {
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    #[command(about = "Undo a transaction, or list them when none is given")]
+    Rollback {
+        txid: Option<String>,
+
+        #[arg(long, help = "Roll back even files changed since the transaction")]
+        force: bool,
+    },
+
     #[command(about = "Show package ownership of files")]
     Owns {
         #[arg(required = true)]
@@ -258,6 +344,43 @@ enum RepoAction {
     },
 }
 
+#[derive(Subcommand)]
+enum KeyAction {
+    #[command(about = "Trust a minisign public key")]
+    Add {
+        file: String,
+    },
+
+    #[command(about = "List trusted and revoked keys")]
+    List,
+
+    #[command(about = "Revoke a key, refusing its signatures from now on")]
+    Revoke {
+        key_id: String,
+    },
+
+    #[command(about = "Make a key pair for signing a repository, as FILE.key and FILE.pub")]
+    Generate {
+        output: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PinAction {
+    #[command(about = "Pin a package, as in foo=1.2.*")]
+    Add {
+        spec: String,
+    },
+
+    #[command(about = "Remove a package's pin")]
+    Remove {
+        name: String,
+    },
+
+    #[command(about = "List pinned and held packages")]
+    List,
+}
+
 #[derive(Subcommand)]
 enum ConfigAction {
     #[command(about = "Show current configuration")]
@@ -281,48 +404,63 @@ enum ConfigAction {
     },
 }
 
+// Report how a command failed, and exit with the code that says so
+fn fail(error: &(dyn Error + 'static), json: bool, quiet: bool) -> ! {
+    let code = exit::code(error);
+    if json {
+        let report = serde_json::json!({
+            "error": { "kind": exit::kind(code), "code": code, "message": error.to_string() }
+        });
+        let _ = display::print_json(&report);
+    } else if code == exit::NOTHING_TO_DO {
+        if !quiet {
+            println!("{} {}", "::".green().bold(), error);
+        }
+    } else if !quiet {
+        eprintln!("{} {}", "Error:".red().bold(), error);
+    }
+    process::exit(code);
+}
+
 fn main() {
     let cli = Cli::parse();
 
-    if cli.no_color {
+    if cli.no_color || cli.json {
         colored::control::set_override(false);
     }
 
     let config = match config::load_config(cli.config.as_deref()) {
         Ok(cfg) => cfg,
-        Err(e) => {
-            eprintln!("{} Failed to load configuration: {}", "Error:".red().bold(), e);
-            process::exit(1);
-        }
+        Err(e) => fail(&*Box::<dyn Error>::from(format!("Failed to load configuration: {}", e)), cli.json, cli.quiet),
     };
 
     let result = match cli.command {
-        Commands::Install { packages, no_deps, as_deps, reinstall } => {
-            commands::install::run(packages, no_deps, as_deps, reinstall, &config, cli.yes)
+        Commands::Install { packages, no_deps, as_deps, reinstall, allow_unsigned, explain } => {
+            commands::install::run(packages, no_deps, as_deps, reinstall, allow_unsigned, explain, &config, cli.yes)
         }
         Commands::Remove { packages, cascade, keep_deps, purge } => {
             commands::remove::run(packages, cascade, keep_deps, purge, &config, cli.yes)
         }
-        Commands::Upgrade { packages, ignore, download_only } => {
-            commands::upgrade::run(packages, ignore, download_only, &config, cli.yes)
+        Commands::Upgrade { packages, ignore, download_only, allow_unsigned, explain } => {
+            commands::upgrade::run(packages, ignore, download_only, allow_unsigned, explain, &config, cli.yes)
         }
         Commands::Search { query, installed, repo } => {
-            commands::search::run(&query, installed, repo, &config)
+            commands::search::run(&query, installed, repo, cli.json, &config)
         }
         Commands::Info { package, files, deps, reverse_deps } => {
-            commands::info::run(&package, files, deps, reverse_deps, &config)
+            commands::info::run(&package, files, deps, reverse_deps, cli.json, &config)
         }
         Commands::List { explicit, deps, orphans, outdated } => {
-            commands::list::run(explicit, deps, orphans, outdated, &config)
+            commands::list::run(explicit, deps, orphans, outdated, cli.json, &config)
         }
-        Commands::Update { force } => {
-            commands::update::run(force, &config)
+        Commands::Update { force, allow_unsigned } => {
+            commands::update::run(force, allow_unsigned, &config)
         }
         Commands::Clean { all, keep } => {
             commands::clean::run(all, keep, &config, cli.yes)
         }
-        Commands::Verify { packages, all, quiet } => {
-            commands::verify::run(packages, all, quiet, &config)
+        Commands::Verify { packages, all, quiet, repair } => {
+            commands::verify::run(packages, all, quiet, repair, cli.json, &config)
         }
         Commands::Repo { action } => {
             match action {
@@ -335,6 +473,36 @@ fn main() {
                 RepoAction::Disable { name } => commands::repo::disable(&name, &config),
             }
         }
+        Commands::RepoBuild { dir, key } => {
+            commands::repo_build::run(&dir, key)
+        }
+        Commands::Serve { dir, bind } => {
+            commands::serve::run(&dir, &bind)
+        }
+        Commands::Key { action } => {
+            match action {
+                KeyAction::Add { file } => commands::key::add(&file, &config, cli.yes),
+                KeyAction::List => commands::key::list(&config),
+                KeyAction::Revoke { key_id } => commands::key::revoke(&key_id, &config, cli.yes),
+                KeyAction::Generate { output } => commands::key::generate(&output),
+            }
+        }
+        Commands::Pin { action } => {
+            match action {
+                PinAction::Add { spec } => commands::pin::add(&spec, &config, cli.config.as_deref()),
+                PinAction::Remove { name } => commands::pin::remove(&name, &config, cli.config.as_deref()),
+                PinAction::List => commands::pin::list(&config),
+            }
+        }
+        Commands::Hold { packages } => {
+            commands::pin::hold(packages, &config, cli.config.as_deref())
+        }
+        Commands::Unhold { packages } => {
+            commands::pin::unhold(packages, &config, cli.config.as_deref())
+        }
+        Commands::Rollback { txid, force } => {
+            commands::rollback::run(txid, force, &config, cli.yes)
+        }
         Commands::Owns { paths } => {
             commands::owns::run(paths, &config)
         }
@@ -348,7 +516,7 @@ fn main() {
             commands::deptree::run(&package, reverse, max_depth, &config)
         }
         Commands::Stats => {
-            commands::stats::run(&config)
+            commands::stats::run(cli.json, &config)
         }
         Commands::Build { spec_file, output, no_deps, sign } => {
             commands::build::run(&spec_file, output, no_deps, sign, &config)
@@ -370,9 +538,6 @@ fn main() {
     };
 
     if let Err(e) = result {
-        if !cli.quiet {
-            eprintln!("{} {}", "Error:".red().bold(), e);
-        }
-        process::exit(1);
+        fail(e.as_ref(), cli.json, cli.quiet);
     }
 }
\ No newline at end of file
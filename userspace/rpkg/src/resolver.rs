@@ -0,0 +1,563 @@
+//! Dependency resolution
+//!
+//! Deciding what to install is a satisfiability problem. Each version of a
+//! package that a repository has or that is installed is a variable, true
+//! if it is to be on the system afterwards. The request, dependencies,
+//! conflicts, replaces, and the rule that one version of a package is
+//! installed at a time are clauses over them. A backtracking search with
+//! unit propagation finds an assignment: it takes the first requirement not
+//! yet met and tries its candidates best first. For a package asked for,
+//! best is the newest version; for any other, the one installed, so that
+//! nothing changes that need not.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::database::{Dependency, IndexEntry};
+
+/// A version of a package, from a repository or installed
+pub struct Candidate {
+    pub entry: IndexEntry,
+    pub repository: String,
+    /// The repository's priority; lower is preferred
+    pub priority: u32,
+    pub installed: bool,
+}
+
+#[derive(Default)]
+pub struct Request {
+    /// What to install, as dependencies
+    pub install: Vec<String>,
+    /// Installed packages to bring to their newest versions
+    pub upgrade: Vec<String>,
+    /// Installed packages to keep at the version installed
+    pub hold: Vec<String>,
+    /// Packages only allowed the versions these permit, even if that means
+    /// going back to an older one
+    pub pins: Vec<Dependency>,
+    pub no_deps: bool,
+    pub allow_downgrade: bool,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// Nothing provides a package asked for
+    NotFound(String),
+    /// Two packages that cannot be installed together, and why
+    Conflict(String),
+    Other(String),
+}
+
+pub struct Solution {
+    /// The candidates to be on the system afterwards, dependencies before
+    /// the packages needing them, with why each is there
+    pub selected: Vec<(usize, String)>,
+    /// The candidates asked for
+    pub requested: HashSet<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Literal {
+    var: usize,
+    positive: bool,
+}
+
+enum Rule {
+    Job(String),
+    Requires(usize, String),
+    Keep(String),
+    Hold(usize),
+    OneVersion(String),
+    Conflicts(usize, usize),
+    Replaces(usize, usize),
+}
+
+struct Clause {
+    literals: Vec<Literal>,
+    rule: Rule,
+    /// The name the clause asks for, whose own packages are preferred over
+    /// ones providing it
+    target: Option<String>,
+}
+
+#[derive(Clone)]
+struct Assignment {
+    values: Vec<Option<bool>>,
+    // The clause that made each variable true or false
+    reasons: Vec<Option<usize>>,
+}
+
+impl Assignment {
+    fn value(&self, literal: Literal) -> Option<bool> {
+        self.values[literal.var].map(|value| value == literal.positive)
+    }
+
+    fn set(&mut self, literal: Literal, reason: Option<usize>) {
+        self.values[literal.var] = Some(literal.positive);
+        self.reasons[literal.var] = reason;
+    }
+}
+
+struct Solver<'a> {
+    candidates: &'a [Candidate],
+    clauses: Vec<Clause>,
+    // The clauses each variable is in
+    occurrences: Vec<Vec<usize>>,
+    newest_first: HashSet<String>,
+    // Clauses found false along the way, for explaining a failure
+    failures: Vec<usize>,
+}
+
+impl<'a> Solver<'a> {
+    fn add(&mut self, literals: Vec<Literal>, rule: Rule, target: Option<String>) {
+        let index = self.clauses.len();
+        for literal in &literals {
+            self.occurrences[literal.var].push(index);
+        }
+        self.clauses.push(Clause { literals, rule, target });
+    }
+
+    fn note_failure(&mut self, clause: usize, assignment: &Assignment) {
+        let mut involved = vec![clause];
+        // and what made the clause's literals false
+        involved.extend(self.clauses[clause].literals.iter().filter_map(|literal| assignment.reasons[literal.var]));
+        for clause in involved {
+            if !self.failures.contains(&clause) {
+                self.failures.push(clause);
+            }
+        }
+    }
+
+    fn propagate(&mut self, assignment: &mut Assignment, mut queue: VecDeque<usize>) -> bool {
+        while let Some(index) = queue.pop_front() {
+            let clause = &self.clauses[index];
+            let mut open = None;
+            let mut open_count = 0;
+            let mut satisfied = false;
+            for &literal in &clause.literals {
+                match assignment.value(literal) {
+                    Some(true) => {
+                        satisfied = true;
+                        break;
+                    }
+                    Some(false) => {}
+                    None => {
+                        open = Some(literal);
+                        open_count += 1;
+                    }
+                }
+            }
+            if satisfied || open_count > 1 {
+                continue;
+            }
+            let Some(literal) = open else {
+                self.note_failure(index, assignment);
+                return false;
+            };
+            assignment.set(literal, Some(index));
+            queue.extend(self.occurrences[literal.var].iter().copied());
+        }
+        true
+    }
+
+    fn rank(&self, var: usize, target: Option<&str>) -> impl Ord {
+        let candidate = &self.candidates[var];
+        let indirect = target.is_some_and(|target| candidate.entry.name != target);
+        let newest = self.newest_first.contains(&candidate.entry.name);
+        let keep = !newest && candidate.installed;
+        (indirect, !keep, Reverse(candidate.entry.version), candidate.priority, !candidate.installed)
+    }
+
+    // The next variable to try making true, and the clause needing it: one
+    // from the first clause that is not met, and cannot be met by leaving
+    // its undecided variables false
+    fn decision(&self, assignment: &Assignment) -> Option<(usize, Literal)> {
+        for (index, clause) in self.clauses.iter().enumerate() {
+            let settled = clause.literals.iter().any(|&literal| match assignment.value(literal) {
+                Some(value) => value,
+                None => !literal.positive,
+            });
+            if settled {
+                continue;
+            }
+            let best = clause
+                .literals
+                .iter()
+                .filter(|&&literal| assignment.value(literal).is_none())
+                .min_by_key(|literal| self.rank(literal.var, clause.target.as_deref()))?;
+            return Some((index, *best));
+        }
+        None
+    }
+
+    fn search(&mut self, mut assignment: Assignment, queue: VecDeque<usize>) -> Option<Assignment> {
+        if !self.propagate(&mut assignment, queue) {
+            return None;
+        }
+        let Some((clause, literal)) = self.decision(&assignment) else {
+            return Some(assignment);
+        };
+        let queue: VecDeque<usize> = self.occurrences[literal.var].iter().copied().collect();
+
+        let mut chosen = assignment.clone();
+        chosen.set(literal, Some(clause));
+        if let Some(solution) = self.search(chosen, queue.clone()) {
+            return Some(solution);
+        }
+        assignment.set(Literal { positive: false, ..literal }, None);
+        self.search(assignment, queue)
+    }
+
+    fn describe(&self, clause: &Clause) -> Problem {
+        let entry = |index: usize| self.candidates[index].entry.to_string();
+        match &clause.rule {
+            Rule::Job(spec) => Problem::Other(format!("{} cannot be installed", spec)),
+            Rule::Requires(index, dependency) => {
+                if clause.literals.len() == 1 {
+                    Problem::Other(format!("{} needs {}, which no repository has", entry(*index), dependency))
+                } else {
+                    Problem::Other(format!("{} needs {}", entry(*index), dependency))
+                }
+            }
+            Rule::Keep(name) => Problem::Other(format!("{} is installed and would have to be removed", name)),
+            Rule::Hold(index) => Problem::Other(format!("{} is held", entry(*index))),
+            Rule::OneVersion(name) => Problem::Other(format!("only one version of {} can be installed", name)),
+            Rule::Conflicts(a, b) => {
+                Problem::Conflict(format!("{} conflicts with {}", entry(*a), self.candidates[*b].entry.name))
+            }
+            Rule::Replaces(a, b) => Problem::Conflict(format!("{} replaces {}", entry(*a), self.candidates[*b].entry.name)),
+        }
+    }
+
+    fn explain(&self, var: usize, reason: Option<usize>) -> String {
+        let Some(reason) = reason else {
+            return String::from("chosen");
+        };
+        match &self.clauses[reason].rule {
+            Rule::Job(spec) if *spec == self.candidates[var].entry.name => String::from("requested"),
+            Rule::Job(spec) => format!("requested as {}", spec),
+            Rule::Requires(index, dependency) => {
+                format!("required by {} ({})", self.candidates[*index].entry, dependency)
+            }
+            Rule::Keep(_) if self.candidates[var].installed => String::from("installed"),
+            Rule::Keep(name) if *name == self.candidates[var].entry.name => String::from("upgrade"),
+            Rule::Keep(name) => format!("replaces {}", name),
+            Rule::Hold(_) => String::from("held"),
+            // Only ever make a variable false
+            Rule::OneVersion(_) | Rule::Conflicts(..) | Rule::Replaces(..) => String::from("chosen"),
+        }
+    }
+}
+
+fn matching<'a>(candidates: &'a [Candidate], dependency: &'a Dependency) -> impl Iterator<Item = usize> + 'a {
+    candidates.iter().enumerate().filter(|(_, candidate)| dependency.matches(&candidate.entry)).map(|(index, _)| index)
+}
+
+// Put `index` in `order` after the chosen packages it depends on
+fn dependencies_first(
+    index: usize,
+    chosen: &[usize],
+    candidates: &[Candidate],
+    visited: &mut HashSet<usize>,
+    order: &mut Vec<usize>,
+) {
+    if !visited.insert(index) {
+        return;
+    }
+    for spec in &candidates[index].entry.depends {
+        if let Ok(dependency) = Dependency::parse(spec) {
+            for &other in chosen.iter().filter(|&&other| dependency.matches(&candidates[other].entry)) {
+                dependencies_first(other, chosen, candidates, visited, order);
+            }
+        }
+    }
+    order.push(index);
+}
+
+fn parse(spec: &str, problems: &mut Vec<Problem>) -> Option<Dependency> {
+    Dependency::parse(spec).map_err(|e| problems.push(Problem::Other(e.to_string()))).ok()
+}
+
+/// Find the packages to have installed to carry out `request`, changing as
+/// little as it can
+pub fn resolve(candidates: &[Candidate], request: &Request) -> Result<Solution, Vec<Problem>> {
+    let mut problems = Vec::new();
+    let installed: HashMap<&str, usize> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, candidate)| candidate.installed)
+        .map(|(index, candidate)| (candidate.entry.name.as_str(), index))
+        .collect();
+    let allowed = |index: usize| {
+        let entry = &candidates[index].entry;
+        if let Some(pin) = request.pins.iter().find(|pin| pin.name == entry.name) {
+            return pin.matches(entry);
+        }
+        request.allow_downgrade
+            || installed.get(entry.name.as_str()).is_none_or(|&i| candidates[i].entry.version <= entry.version)
+    };
+
+    let mut jobs = Vec::new();
+    for spec in &request.install {
+        let Some(dependency) = parse(spec, &mut problems) else {
+            continue;
+        };
+        let found: Vec<usize> = matching(candidates, &dependency).filter(|&index| allowed(index)).collect();
+        if found.is_empty() {
+            problems.push(Problem::NotFound(spec.clone()));
+        }
+        jobs.push((spec.clone(), dependency, found));
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    // Only the packages that could be wanted take part
+    let mut reachable = vec![false; candidates.len()];
+    let mut queue: VecDeque<usize> = jobs.iter().flat_map(|(_, _, found)| found.iter().copied()).collect();
+    queue.extend(installed.values().copied());
+    for name in &request.upgrade {
+        queue.extend((0..candidates.len()).filter(|&index| candidates[index].entry.name == *name));
+    }
+    let mut requires: Vec<(usize, String, Vec<usize>)> = Vec::new();
+    while let Some(index) = queue.pop_front() {
+        if reachable[index] || !allowed(index) {
+            continue;
+        }
+        reachable[index] = true;
+        if request.no_deps {
+            continue;
+        }
+        for spec in &candidates[index].entry.depends {
+            let Some(dependency) = parse(spec, &mut problems) else {
+                continue;
+            };
+            let providers: Vec<usize> = matching(candidates, &dependency).filter(|&i| allowed(i)).collect();
+            queue.extend(providers.iter().copied());
+            requires.push((index, spec.clone(), providers));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut solver = Solver {
+        candidates,
+        clauses: Vec::new(),
+        occurrences: vec![Vec::new(); candidates.len()],
+        newest_first: request.upgrade.iter().cloned().collect(),
+        failures: Vec::new(),
+    };
+    let positive = |var| Literal { var, positive: true };
+    let negative = |var| Literal { var, positive: false };
+    for (spec, dependency, found) in &jobs {
+        solver.newest_first.extend(found.iter().map(|&index| candidates[index].entry.name.clone()));
+        solver.add(found.iter().map(|&index| positive(index)).collect(), Rule::Job(spec.clone()), Some(dependency.name.clone()));
+    }
+    for name in &request.hold {
+        if let Some(&index) = installed.get(name.as_str()) {
+            solver.add(vec![positive(index)], Rule::Hold(index), None);
+        }
+    }
+
+    // What replaces each installed package
+    let mut replacements: HashMap<&str, Vec<usize>> = HashMap::new();
+    for index in (0..candidates.len()).filter(|&index| reachable[index]) {
+        let entry = &candidates[index].entry;
+        for (specs, replaces) in [(&entry.conflicts, false), (&entry.replaces, true)] {
+            for spec in specs {
+                let Some(dependency) = parse(spec, &mut problems) else {
+                    continue;
+                };
+                for other in matching(candidates, &dependency) {
+                    if !reachable[other] || candidates[other].entry.name == entry.name {
+                        continue;
+                    }
+                    let rule = if replaces { Rule::Replaces(index, other) } else { Rule::Conflicts(index, other) };
+                    solver.add(vec![negative(index), negative(other)], rule, None);
+                    if replaces && candidates[other].installed {
+                        replacements.entry(candidates[other].entry.name.as_str()).or_default().push(index);
+                    }
+                }
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let mut names: Vec<&str> = installed.keys().copied().collect();
+    names.sort();
+    for name in names {
+        let mut literals: Vec<Literal> =
+            (0..candidates.len()).filter(|&i| reachable[i] && candidates[i].entry.name == name).map(positive).collect();
+        literals.extend(replacements.get(name).into_iter().flatten().map(|&index| positive(index)));
+        solver.add(literals, Rule::Keep(name.to_string()), Some(name.to_string()));
+    }
+    for (index, spec, providers) in requires {
+        let mut literals = vec![negative(index)];
+        literals.extend(providers.iter().map(|&provider| positive(provider)));
+        let target = Dependency::parse(&spec).ok().map(|dependency| dependency.name);
+        solver.add(literals, Rule::Requires(index, spec), target);
+    }
+    let mut versions: HashMap<&str, Vec<usize>> = HashMap::new();
+    for index in (0..candidates.len()).filter(|&index| reachable[index]) {
+        versions.entry(candidates[index].entry.name.as_str()).or_default().push(index);
+    }
+    for (name, indexes) in versions {
+        for (n, &a) in indexes.iter().enumerate() {
+            for &b in &indexes[n + 1..] {
+                solver.add(vec![negative(a), negative(b)], Rule::OneVersion(name.to_string()), None);
+            }
+        }
+    }
+
+    let mut assignment = Assignment { values: vec![None; candidates.len()], reasons: vec![None; candidates.len()] };
+    for index in (0..candidates.len()).filter(|&index| !reachable[index]) {
+        assignment.values[index] = Some(false);
+    }
+    let queue = (0..solver.clauses.len()).collect();
+    let Some(assignment) = solver.search(assignment, queue) else {
+        let failures = std::mem::take(&mut solver.failures);
+        return Err(failures.into_iter().map(|clause| solver.describe(&solver.clauses[clause])).collect());
+    };
+
+    let chosen: Vec<usize> = (0..candidates.len()).filter(|&index| assignment.values[index] == Some(true)).collect();
+    let requested =
+        jobs.iter().flat_map(|(_, _, found)| found.iter().copied()).filter(|&index| chosen.contains(&index)).collect();
+
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    for &index in &chosen {
+        dependencies_first(index, &chosen, candidates, &mut visited, &mut order);
+    }
+    let selected = order.into_iter().map(|index| (index, solver.explain(index, assignment.reasons[index]))).collect();
+    Ok(Solution { selected, requested })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn candidate(entry: serde_json::Value, installed: bool) -> Candidate {
+        Candidate { entry: serde_json::from_value(entry).unwrap(), repository: String::from("core"), priority: 0, installed }
+    }
+
+    fn install(names: &[&str]) -> Request {
+        Request { install: names.iter().map(|name| name.to_string()).collect(), ..Request::default() }
+    }
+
+    // What the solution has installed, in order
+    fn selected(candidates: &[Candidate], solution: &Solution) -> Vec<String> {
+        solution.selected.iter().map(|(index, _)| candidates[*index].entry.to_string()).collect()
+    }
+
+    fn problems(result: Result<Solution, Vec<Problem>>) -> Vec<String> {
+        let Err(problems) = result else {
+            panic!("resolved");
+        };
+        problems
+            .into_iter()
+            .map(|problem| match problem {
+                Problem::NotFound(name) => format!("not found: {}", name),
+                Problem::Conflict(reason) | Problem::Other(reason) => reason,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn installs_the_newest_after_its_dependencies() {
+        let candidates = [
+            candidate(json!({"name": "app", "version": "1.0.0", "depends": ["lib>=1"]}), false),
+            candidate(json!({"name": "app", "version": "2.0.0", "depends": ["lib>=2"]}), false),
+            candidate(json!({"name": "lib", "version": "1.5.0"}), false),
+            candidate(json!({"name": "lib", "version": "2.1.0"}), false),
+        ];
+        let solution = resolve(&candidates, &install(&["app"])).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-2.1.0", "app-2.0.0"]);
+        assert_eq!(solution.requested, HashSet::from([1]));
+        assert_eq!(solution.selected[0].1, "required by app-2.0.0 (lib>=2)");
+    }
+
+    #[test]
+    fn keeps_an_installed_dependency_that_will_do() {
+        let candidates = [
+            candidate(json!({"name": "app", "version": "1.0.0", "depends": ["lib>=1"]}), false),
+            candidate(json!({"name": "lib", "version": "1.0.0"}), true),
+            candidate(json!({"name": "lib", "version": "1.2.0"}), false),
+        ];
+        let solution = resolve(&candidates, &install(&["app"])).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-1.0.0", "app-1.0.0"]);
+    }
+
+    #[test]
+    fn upgrades_to_the_newest_unless_held() {
+        let candidates = [
+            candidate(json!({"name": "lib", "version": "1.0.0"}), true),
+            candidate(json!({"name": "lib", "version": "1.2.0"}), false),
+        ];
+        let upgrade = Request { upgrade: vec![String::from("lib")], ..Request::default() };
+        let solution = resolve(&candidates, &upgrade).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-1.2.0"]);
+
+        let held = Request { hold: vec![String::from("lib")], ..upgrade };
+        let solution = resolve(&candidates, &held).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-1.0.0"]);
+    }
+
+    #[test]
+    fn goes_back_only_when_pinned_or_allowed() {
+        let candidates = [
+            candidate(json!({"name": "lib", "version": "1.0.0"}), false),
+            candidate(json!({"name": "lib", "version": "2.0.0"}), true),
+        ];
+        assert_eq!(problems(resolve(&candidates, &install(&["lib<2"]))), ["not found: lib<2"]);
+
+        let pinned = Request { pins: vec![Dependency::parse("lib<2").unwrap()], ..install(&["lib"]) };
+        let solution = resolve(&candidates, &pinned).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-1.0.0"]);
+
+        let allowed = Request { allow_downgrade: true, ..install(&["lib<2"]) };
+        let solution = resolve(&candidates, &allowed).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["lib-1.0.0"]);
+    }
+
+    #[test]
+    fn meets_a_dependency_with_what_provides_it() {
+        let candidates = [
+            candidate(json!({"name": "app", "version": "1.0.0", "depends": ["mailer>=1"]}), false),
+            candidate(json!({"name": "postfix", "version": "3.0.0", "provides": ["mailer=1.0"]}), false),
+            candidate(json!({"name": "sendmail", "version": "8.0.0", "provides": ["mailer"]}), false),
+        ];
+        let solution = resolve(&candidates, &install(&["app"])).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["postfix-3.0.0", "app-1.0.0"]);
+    }
+
+    #[test]
+    fn reports_a_conflict_with_an_installed_package() {
+        let candidates = [
+            candidate(json!({"name": "app", "version": "1.0.0", "conflicts": ["old"]}), false),
+            candidate(json!({"name": "old", "version": "1.0.0"}), true),
+        ];
+        let problems = problems(resolve(&candidates, &install(&["app"])));
+        assert!(problems.contains(&String::from("app-1.0.0 conflicts with old")), "{:?}", problems);
+    }
+
+    #[test]
+    fn replacing_removes_the_installed_package() {
+        let candidates = [
+            candidate(json!({"name": "new", "version": "1.0.0", "replaces": ["old"]}), false),
+            candidate(json!({"name": "old", "version": "1.0.0"}), true),
+        ];
+        let solution = resolve(&candidates, &install(&["new"])).unwrap();
+        assert_eq!(selected(&candidates, &solution), ["new-1.0.0"]);
+    }
+
+    #[test]
+    fn explains_a_missing_dependency() {
+        let candidates = [candidate(json!({"name": "app", "version": "1.0.0", "depends": ["lib"]}), false)];
+        assert_eq!(problems(resolve(&candidates, &install(&["nothing"]))), ["not found: nothing"]);
+        let problems = problems(resolve(&candidates, &install(&["app"])));
+        assert!(problems.contains(&String::from("app-1.0.0 needs lib, which no repository has")), "{:?}", problems);
+    }
+}
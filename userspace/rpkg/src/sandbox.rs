@@ -0,0 +1,383 @@
+//! Running packages' hooks and triggers apart from the rest of the system
+//!
+//! In the sandbox, which is on unless `hooks.sandbox` turns it off, a
+//! program runs in a temporary root of its own, in a network namespace
+//! with no way out to the network. The root is a fresh directory holding
+//! read-only views of the system's top-level directories, a few devices
+//! from /dev, an empty /tmp that is also the program's home, and, where
+//! they are on the system, the directories it is given to write to. There
+//! is no /proc, /sys, /run or /home, and all of it is removed once the
+//! program exits. When the directory to write to is `/` itself, the
+//! top-level directories are writable instead.
+//!
+//! Its environment is cleared but for PATH and what rpkg passes it, and it
+//! is held to the CPU time, memory and open file limits in the
+//! configuration. When rpkg is not run as root, the namespaces are made in
+//! a user namespace that maps the user to themselves.
+//!
+//! With or without the sandbox, a program still running after
+//! `hooks.timeout_seconds` is killed, along with anything it started.
+
+use std::error::Error;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::config::HooksConfig;
+
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+// Inside the temporary root, the program's home and temporary directory
+const HOME: &str = "/tmp";
+// Top-level directories left out of the temporary root
+const HIDDEN: &[&str] = &["proc", "sys", "dev", "run", "tmp", "home", "root", "mnt", "media", "lost+found"];
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom"];
+// How often a running program is checked on
+const POLL: Duration = Duration::from_millis(20);
+
+pub struct Sandbox {
+    command: Command,
+    // The temporary root, until the program exits
+    root: Option<tempfile::TempDir>,
+    // Directories shown in the temporary root at their own paths, and
+    // whether they may be written to
+    binds: Vec<(PathBuf, bool)>,
+    dir: Option<PathBuf>,
+    config: HooksConfig,
+}
+
+// A bind mount into the temporary root
+struct Mount {
+    source: CString,
+    target: CString,
+    read_only: bool,
+}
+
+// Between fork and exec only async-signal-safe calls may be made, so these
+// take what they need made beforehand
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+unsafe fn write_file(path: &CString, data: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, data.as_ptr().cast(), data.len());
+    libc::close(fd);
+    if written != data.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+unsafe fn isolate(maps: &[(CString, Vec<u8>)]) -> io::Result<()> {
+    if libc::unshare(libc::CLONE_NEWNET | libc::CLONE_NEWNS) == 0 {
+        return Ok(());
+    }
+    // Only root may; anyone else needs a user namespace to do it in
+    check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET | libc::CLONE_NEWNS))?;
+    for (path, map) in maps {
+        write_file(path, map)?;
+    }
+    Ok(())
+}
+
+unsafe fn bind(mount: &Mount) -> io::Result<()> {
+    check(libc::mount(mount.source.as_ptr(), mount.target.as_ptr(), ptr::null(), libc::MS_BIND, ptr::null()))?;
+    if !mount.read_only {
+        return Ok(());
+    }
+    // A user namespace may only remount with the flags the mount had kept
+    let mut stat: libc::statvfs = mem::zeroed();
+    check(libc::statvfs(mount.target.as_ptr(), &mut stat))?;
+    let kept = [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ];
+    let flags = kept.iter().filter(|(st, _)| stat.f_flag & st != 0).fold(0, |flags, (_, ms)| flags | ms);
+    check(libc::mount(
+        ptr::null(),
+        mount.target.as_ptr(),
+        ptr::null(),
+        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags,
+        ptr::null(),
+    ))
+}
+
+// Build the temporary root, the first mount being the root onto itself,
+// and make it the root in place of the system's
+unsafe fn enter(root: &CString, mounts: &[Mount], dir: &CString) -> io::Result<()> {
+    // Nothing mounted here may show through to the system
+    check(libc::mount(ptr::null(), c"/".as_ptr(), ptr::null(), libc::MS_REC | libc::MS_PRIVATE, ptr::null()))?;
+    for mount in mounts {
+        bind(mount)?;
+    }
+    check(libc::chdir(root.as_ptr()))?;
+    check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int)?;
+    // The system's root is now beneath it; let go of that
+    check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+    check(libc::chdir(dir.as_ptr()))
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// Where a path on the system is in the temporary root
+fn within(root: &Path, path: &Path) -> PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+// Lay out the temporary root and list what is to be mounted in it
+fn prepare(root: &Path, binds: &[(PathBuf, bool)]) -> io::Result<Vec<Mount>> {
+    let mut mounts = vec![Mount { source: c_path(root)?, target: c_path(root)?, read_only: false }];
+    let mut binds = binds
+        .iter()
+        .map(|(path, writable)| Ok((fs::canonicalize(path)?, *writable)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let system_writable = binds.iter().any(|(path, writable)| *writable && path == Path::new("/"));
+    binds.retain(|(path, _)| path != Path::new("/"));
+    // Outer directories before what is mounted within them
+    binds.sort_by_key(|(path, _)| path.components().count());
+
+    for entry in fs::read_dir("/")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if HIDDEN.iter().any(|hidden| name == OsStr::new(hidden)) {
+            continue;
+        }
+        let target = root.join(&name);
+        let kind = entry.file_type()?;
+        if kind.is_symlink() {
+            // As in /bin -> usr/bin
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else if kind.is_dir() {
+            fs::create_dir(&target)?;
+            mounts.push(Mount { source: c_path(&entry.path())?, target: c_path(&target)?, read_only: !system_writable });
+        }
+    }
+
+    fs::create_dir(root.join("dev"))?;
+    for device in DEVICES {
+        let source = Path::new("/dev").join(device);
+        if source.exists() {
+            let target = root.join("dev").join(device);
+            File::create(&target)?;
+            mounts.push(Mount { source: c_path(&source)?, target: c_path(&target)?, read_only: false });
+        }
+    }
+
+    for (path, writable) in &binds {
+        let target = within(root, path);
+        // Under a directory already shown, this is hidden by the mount
+        fs::create_dir_all(&target)?;
+        mounts.push(Mount { source: c_path(path)?, target: c_path(&target)?, read_only: !writable });
+    }
+    Ok(mounts)
+}
+
+impl Sandbox {
+    pub fn new(program: impl AsRef<OsStr>, config: &HooksConfig) -> Result<Sandbox, Box<dyn Error>> {
+        let program = program.as_ref();
+        let mut command = Command::new(program);
+        command.stdin(Stdio::null());
+        // Its own process group, so a timeout can kill all of it
+        command.process_group(0);
+        let mut root = None;
+        let mut binds = Vec::new();
+
+        if config.sandbox {
+            let dir = tempfile::Builder::new().prefix("rpkg-hook-").tempdir()?;
+            fs::create_dir(within(dir.path(), Path::new(HOME)))?;
+            command.env_clear().env("PATH", PATH).env("HOME", HOME).env("TMPDIR", HOME);
+            root = Some(dir);
+            // A program named by its path must be found in the root
+            if let Some(parent) = Path::new(program).parent().filter(|parent| parent.is_absolute()) {
+                binds.push((parent.to_path_buf(), false));
+            }
+        }
+
+        Ok(Sandbox {
+            command,
+            root,
+            binds,
+            dir: None,
+            config: config.clone(),
+        })
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Sandbox
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.command.args(args);
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: impl AsRef<OsStr>) -> Sandbox {
+        self.command.env(key, value);
+        self
+    }
+
+    pub fn current_dir(mut self, dir: &Path) -> Sandbox {
+        self.command.current_dir(dir);
+        self.dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// Let it write to a directory, which the sandbox otherwise leaves out
+    /// or shows read-only
+    pub fn writable(mut self, dir: &Path) -> Sandbox {
+        self.binds.push((dir.to_path_buf(), true));
+        self
+    }
+
+    /// Run it to the end, or until it is killed for taking too long
+    pub fn run(mut self) -> Result<ExitStatus, Box<dyn Error>> {
+        if let Some(root) = &self.root {
+            let mounts = prepare(root.path(), &self.binds)?;
+            let root = c_path(root.path())?;
+            let dir = c_path(self.dir.as_deref().unwrap_or(Path::new("/")))?;
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            let maps = vec![
+                (CString::new("/proc/self/setgroups")?, b"deny".to_vec()),
+                (CString::new("/proc/self/uid_map")?, format!("{} {} 1", uid, uid).into_bytes()),
+                (CString::new("/proc/self/gid_map")?, format!("{} {} 1", gid, gid).into_bytes()),
+            ];
+            let limits = [
+                (libc::RLIMIT_CPU, self.config.cpu_seconds),
+                (libc::RLIMIT_AS, self.config.memory_mb.saturating_mul(1024 * 1024)),
+                (libc::RLIMIT_NOFILE, self.config.open_files),
+            ];
+            unsafe {
+                self.command.pre_exec(move || {
+                    isolate(&maps)?;
+                    enter(&root, &mounts, &dir)?;
+                    for (resource, value) in limits.iter().filter(|(_, value)| *value > 0) {
+                        let limit = libc::rlimit { rlim_cur: *value, rlim_max: *value };
+                        check(libc::setrlimit(*resource, &limit))?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        let mut child = self.command.spawn()?;
+        let timeout = Duration::from_secs(self.config.timeout_seconds);
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if !timeout.is_zero() && start.elapsed() >= timeout {
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                child.wait()?;
+                return Err(format!("killed after running for {} seconds", timeout.as_secs()).into());
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn config() -> HooksConfig {
+        HooksConfig { timeout_seconds: 30, ..HooksConfig::default() }
+    }
+
+    fn sh(script: &str, config: &HooksConfig) -> Sandbox {
+        Sandbox::new("/bin/sh", config).unwrap().args(["-c", script])
+    }
+
+    #[test]
+    fn runs_in_a_temporary_root() {
+        let root = tempfile::tempdir().unwrap();
+        let status = sh(
+            "echo kept > kept && echo gone > /gone && echo gone > /tmp/gone \
+             && ! touch /etc/rpkg-sandbox-test && [ ! -e /proc/self ] && [ ! -e /home ] \
+             && [ \"$(cat /dev/null)\" = \"\" ] && [ \"$PWD\" = \"$RPKG_ROOT\" ]",
+            &config(),
+        )
+        .writable(root.path())
+        .current_dir(root.path())
+        .env("RPKG_ROOT", root.path())
+        .run()
+        .unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(root.path().join("kept")).unwrap(), "kept\n");
+        assert!(!Path::new("/gone").exists());
+        assert!(!Path::new("/etc/rpkg-sandbox-test").exists());
+    }
+
+    #[test]
+    fn shows_the_program_and_what_it_may_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hook");
+        fs::write(&script, "#!/bin/sh\n[ -r /etc/passwd ] && ! touch \"$0.new\"\n").unwrap();
+        fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        assert!(Sandbox::new(&script, &config()).unwrap().run().unwrap().success());
+    }
+
+    #[test]
+    fn clears_the_environment_and_gives_a_fresh_home() {
+        // cargo test sets this for us
+        assert!(std::env::var_os("CARGO_MANIFEST_DIR").is_some());
+        let script = "[ -z \"$CARGO_MANIFEST_DIR\" ] && [ \"$HOME\" = /tmp ] && [ \"$TMPDIR\" = /tmp ] \
+                      && [ -z \"$(ls -A /tmp)\" ] && [ \"$RPKG_PACKAGE\" = foo ]";
+        assert!(sh(script, &config()).env("RPKG_PACKAGE", "foo").run().unwrap().success());
+    }
+
+    #[test]
+    fn has_no_network() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connect = format!("echo > /dev/tcp/127.0.0.1/{}", port);
+        let status = Sandbox::new("/bin/bash", &config()).unwrap().args(["-c", &connect]).run().unwrap();
+        assert!(!status.success());
+
+        // Outside the sandbox, the same connects
+        let config = HooksConfig { sandbox: false, ..config() };
+        let status = Sandbox::new("/bin/bash", &config).unwrap().args(["-c", &connect]).run().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn holds_it_to_the_limits() {
+        let config = HooksConfig { open_files: 16, cpu_seconds: 7, ..config() };
+        assert!(sh("[ \"$(ulimit -n)\" = 16 ] && [ \"$(ulimit -t)\" = 7 ]", &config).run().unwrap().success());
+    }
+
+    #[test]
+    fn kills_everything_it_started_after_the_timeout() {
+        let config = HooksConfig { timeout_seconds: 1, ..config() };
+        let start = Instant::now();
+        let error = sh("sleep 30 & wait", &config).run().unwrap_err();
+        assert_eq!(error.to_string(), "killed after running for 1 seconds");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn without_the_sandbox_runs_on_the_system() {
+        let config = HooksConfig { sandbox: false, ..config() };
+        assert!(sh("[ -e /proc/self ] && [ -n \"$CARGO_MANIFEST_DIR\" ]", &config).run().unwrap().success());
+    }
+}
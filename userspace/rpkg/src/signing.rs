@@ -0,0 +1,471 @@
+//! Minisign signatures over repository indexes and package archives
+//!
+//! A repository signs its index and each package with a minisign key, and
+//! serves each signature next to its file, with `.minisig` added to the
+//! name. The keys trusted to sign are minisign public key files in the
+//! keyring directory, one per key. A revoked key's ID is listed in the
+//! keyring's `revoked` file, and its signatures are refused even if the
+//! key is added again. OpenPGP signatures are not understood.
+//!
+//! Secret keys, for signing a repository, are minisign secret key files,
+//! with the key encrypted under a password or, if it was made without one,
+//! not.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub const SIGNATURE_SUFFIX: &str = ".minisig";
+
+const ALGORITHM_ED25519: [u8; 2] = *b"Ed";
+// Signatures over the file's BLAKE2b-512 hash, which minisign makes by default
+const ALGORITHM_ED25519_HASHED: [u8; 2] = *b"ED";
+const UNTRUSTED_PREFIX: &str = "untrusted comment: ";
+const TRUSTED_PREFIX: &str = "trusted comment: ";
+const REVOKED_FILE: &str = "revoked";
+const KEY_EXTENSION: &str = "pub";
+const KDF_SCRYPT: [u8; 2] = *b"Sc";
+const KDF_NONE: [u8; 2] = [0, 0];
+const CHECKSUM_BLAKE2B: [u8; 2] = *b"B2";
+// The work minisign has scrypt do to encrypt a new key
+const OPSLIMIT: u64 = 33_554_432;
+const MEMLIMIT: u64 = 1_073_741_824;
+// Asked for when a secret key is encrypted and this is not set
+const PASSWORD_VARIABLE: &str = "RPKG_KEY_PASSWORD";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyId([u8; 8]);
+
+impl KeyId {
+    pub fn parse(text: &str) -> Option<KeyId> {
+        u64::from_str_radix(text.trim(), 16).ok().map(|id| KeyId(id.to_le_bytes()))
+    }
+}
+
+// As minisign prints it
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016X}", u64::from_le_bytes(self.0))
+    }
+}
+
+pub struct PublicKey {
+    pub id: KeyId,
+    pub comment: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// A minisign public key file, or the bare key line from one
+    pub fn parse(text: &str) -> Result<PublicKey, Box<dyn Error>> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        let first = lines.next().ok_or("empty public key")?;
+        let (comment, encoded) = match first.strip_prefix(UNTRUSTED_PREFIX) {
+            Some(comment) => (comment.to_string(), lines.next().ok_or("no key after the comment")?),
+            None => (String::new(), first),
+        };
+        let bytes = BASE64.decode(encoded)?;
+        if bytes.len() != 42 || bytes[..2] != ALGORITHM_ED25519 {
+            return Err("not a minisign Ed25519 public key".into());
+        }
+        let id = KeyId(bytes[2..10].try_into()?);
+        let key = VerifyingKey::from_bytes(&bytes[10..].try_into()?)?;
+        Ok(PublicKey { id, comment, key })
+    }
+
+    pub fn to_file(&self) -> String {
+        let mut bytes = ALGORITHM_ED25519.to_vec();
+        bytes.extend_from_slice(&self.id.0);
+        bytes.extend_from_slice(self.key.as_bytes());
+        let comment = if self.comment.is_empty() {
+            format!("minisign public key {}", self.id)
+        } else {
+            self.comment.clone()
+        };
+        format!("{}{}\n{}\n", UNTRUSTED_PREFIX, comment, BASE64.encode(bytes))
+    }
+}
+
+pub struct SecretKey {
+    pub id: KeyId,
+    key: SigningKey,
+}
+
+// The scrypt parameters libsodium picks for these limits
+fn scrypt_params(opslimit: u64, memlimit: u64) -> Result<scrypt::Params, Box<dyn Error>> {
+    let opslimit = opslimit.max(32768);
+    let r = 8u64;
+    let (log_n, p) = if opslimit < memlimit / 32 {
+        let max_n = opslimit / (r * 4);
+        ((1..63).find(|&log_n| 1u64 << log_n > max_n / 2).unwrap(), 1)
+    } else {
+        let max_n = memlimit / (r * 128);
+        let log_n = (1..63).find(|&log_n| 1u64 << log_n > max_n / 2).unwrap();
+        let max_rp = ((opslimit / 4) >> log_n).min(0x3fff_ffff);
+        (log_n, (max_rp / r).max(1))
+    };
+    // The length is only for password hashes; scrypt() is told its own
+    Ok(scrypt::Params::new(log_n as u8, r as u32, p as u32, scrypt::Params::RECOMMENDED_LEN)
+        .map_err(|_| "the key's encryption parameters are out of range")?)
+}
+
+// What the key material is encrypted with
+fn keystream(password: &str, salt: &[u8], opslimit: u64, memlimit: u64) -> Result<[u8; 104], Box<dyn Error>> {
+    let mut stream = [0; 104];
+    scrypt::scrypt(password.as_bytes(), salt, &scrypt_params(opslimit, memlimit)?, &mut stream)
+        .map_err(|_| "cannot derive the key's encryption key")?;
+    Ok(stream)
+}
+
+fn key_checksum(id: &KeyId, secret: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2b::<U32>::new();
+    hasher.update(ALGORITHM_ED25519);
+    hasher.update(id.0);
+    hasher.update(secret);
+    hasher.finalize().into()
+}
+
+impl SecretKey {
+    pub fn generate() -> Result<SecretKey, Box<dyn Error>> {
+        let mut random = [0u8; 40];
+        File::open("/dev/urandom")?.read_exact(&mut random)?;
+        let id = KeyId(random[..8].try_into()?);
+        let key = SigningKey::from_bytes(&random[8..].try_into()?);
+        Ok(SecretKey { id, key })
+    }
+
+    /// A minisign secret key file, asking for its password if it has one
+    pub fn load(path: &Path) -> Result<SecretKey, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        SecretKey::parse(&text, || password(&format!("Password for {}: ", path.display()), false))
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(
+        text: &str,
+        password: impl FnOnce() -> Result<String, Box<dyn Error>>,
+    ) -> Result<SecretKey, Box<dyn Error>> {
+        let encoded = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_PREFIX))
+            .ok_or("empty secret key")?;
+        let bytes = BASE64.decode(encoded)?;
+        if bytes.len() != 158 || bytes[..2] != ALGORITHM_ED25519 || bytes[4..6] != CHECKSUM_BLAKE2B {
+            return Err("not a minisign Ed25519 secret key".into());
+        }
+        let mut secret: [u8; 104] = bytes[54..].try_into()?;
+        match [bytes[2], bytes[3]] {
+            KDF_NONE => {}
+            KDF_SCRYPT => {
+                let opslimit = u64::from_le_bytes(bytes[38..46].try_into()?);
+                let memlimit = u64::from_le_bytes(bytes[46..54].try_into()?);
+                let stream = keystream(&password()?, &bytes[6..38], opslimit, memlimit)?;
+                secret.iter_mut().zip(stream).for_each(|(byte, key)| *byte ^= key);
+            }
+            _ => return Err("unknown key encryption".into()),
+        }
+        let id = KeyId(secret[..8].try_into()?);
+        if key_checksum(&id, &secret[8..72]) != secret[72..] {
+            return Err("wrong password".into());
+        }
+        let key = SigningKey::from_bytes(&secret[8..40].try_into()?);
+        if key.verifying_key().as_bytes()[..] != secret[40..72] {
+            return Err("damaged secret key".into());
+        }
+        Ok(SecretKey { id, key })
+    }
+
+    /// The key as a minisign secret key file, encrypted unless `password`
+    /// is empty
+    pub fn to_file(&self, password: &str) -> Result<String, Box<dyn Error>> {
+        let mut secret = self.id.0.to_vec();
+        secret.extend_from_slice(&self.key.to_keypair_bytes());
+        secret.extend_from_slice(&key_checksum(&self.id, &secret[8..72]));
+
+        let mut salt = [0u8; 32];
+        let mut bytes = ALGORITHM_ED25519.to_vec();
+        let (opslimit, memlimit) = if password.is_empty() {
+            bytes.extend_from_slice(&KDF_NONE);
+            (0, 0)
+        } else {
+            File::open("/dev/urandom")?.read_exact(&mut salt)?;
+            let stream = keystream(password, &salt, OPSLIMIT, MEMLIMIT)?;
+            secret.iter_mut().zip(stream).for_each(|(byte, key)| *byte ^= key);
+            bytes.extend_from_slice(&KDF_SCRYPT);
+            (OPSLIMIT, MEMLIMIT)
+        };
+        bytes.extend_from_slice(&CHECKSUM_BLAKE2B);
+        bytes.extend_from_slice(&salt);
+        bytes.extend_from_slice(&opslimit.to_le_bytes());
+        bytes.extend_from_slice(&memlimit.to_le_bytes());
+        bytes.extend_from_slice(&secret);
+        Ok(format!("{}minisign secret key {}\n{}\n", UNTRUSTED_PREFIX, self.id, BASE64.encode(bytes)))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey { id: self.id, comment: String::new(), key: self.key.verifying_key() }
+    }
+
+    /// A .minisig file for `data`, as the file `name`
+    pub fn sign(&self, data: &[u8], name: &str) -> String {
+        let signature = self.key.sign(&Blake2b512::digest(data));
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let trusted_comment = format!("timestamp:{}\tfile:{}\thashed", time, name);
+        let mut global = signature.to_bytes().to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.key.sign(&global);
+
+        let mut bytes = ALGORITHM_ED25519_HASHED.to_vec();
+        bytes.extend_from_slice(&self.id.0);
+        bytes.extend_from_slice(&signature.to_bytes());
+        format!(
+            "{}signature from rpkg secret key {}\n{}\n{}{}\n{}\n",
+            UNTRUSTED_PREFIX,
+            self.id,
+            BASE64.encode(bytes),
+            TRUSTED_PREFIX,
+            trusted_comment,
+            BASE64.encode(global_signature.to_bytes())
+        )
+    }
+}
+
+/// A secret key's password, from the environment or asked for, twice if
+/// it is a new one
+pub fn password(prompt: &str, new: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(password) = std::env::var(PASSWORD_VARIABLE) {
+        return Ok(password);
+    }
+    let password = rpassword::prompt_password(prompt)?;
+    if new && !password.is_empty() && rpassword::prompt_password("Again: ")? != password {
+        return Err("the passwords differ".into());
+    }
+    Ok(password)
+}
+
+// A .minisig file
+struct SignatureFile {
+    hashed: bool,
+    key_id: KeyId,
+    signature: Signature,
+    trusted_comment: String,
+    // Over the signature and the trusted comment
+    global_signature: Signature,
+}
+
+impl SignatureFile {
+    fn parse(data: &[u8]) -> Result<SignatureFile, Box<dyn Error>> {
+        let text = std::str::from_utf8(data).map_err(|_| "not a minisign signature")?;
+        let mut lines = text.lines().map(str::trim_end);
+        lines.next().filter(|line| line.starts_with(UNTRUSTED_PREFIX)).ok_or("not a minisign signature")?;
+        let bytes = BASE64.decode(lines.next().ok_or("truncated signature")?)?;
+        if bytes.len() != 74 {
+            return Err("malformed signature".into());
+        }
+        let algorithm = [bytes[0], bytes[1]];
+        if algorithm != ALGORITHM_ED25519 && algorithm != ALGORITHM_ED25519_HASHED {
+            return Err("unknown signature algorithm".into());
+        }
+        let trusted_comment = lines
+            .next()
+            .and_then(|line| line.strip_prefix(TRUSTED_PREFIX))
+            .ok_or("signature has no trusted comment")?
+            .to_string();
+        let global = BASE64.decode(lines.next().ok_or("truncated signature")?)?;
+        Ok(SignatureFile {
+            hashed: algorithm == ALGORITHM_ED25519_HASHED,
+            key_id: KeyId(bytes[2..10].try_into()?),
+            signature: Signature::from_slice(&bytes[10..])?,
+            trusted_comment,
+            global_signature: Signature::from_slice(&global)?,
+        })
+    }
+}
+
+pub struct Keyring {
+    dir: PathBuf,
+    keys: Vec<(PathBuf, PublicKey)>,
+    revoked: Vec<KeyId>,
+}
+
+impl Keyring {
+    pub fn load(dir: &str) -> Result<Keyring, Box<dyn Error>> {
+        let dir = PathBuf::from(dir);
+        let mut keys = Vec::new();
+        let mut revoked = Vec::new();
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.extension().is_none_or(|extension| extension != KEY_EXTENSION) {
+                    continue;
+                }
+                let key = PublicKey::parse(&fs::read_to_string(&path)?)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                keys.push((path, key));
+            }
+            let revoked_path = dir.join(REVOKED_FILE);
+            if revoked_path.exists() {
+                revoked = fs::read_to_string(&revoked_path)?.lines().filter_map(KeyId::parse).collect();
+            }
+        }
+        Ok(Keyring { dir, keys, revoked })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &PublicKey> {
+        self.keys.iter().map(|(_, key)| key)
+    }
+
+    pub fn revoked(&self) -> &[KeyId] {
+        &self.revoked
+    }
+
+    pub fn add(&mut self, key: PublicKey) -> Result<(), Box<dyn Error>> {
+        if self.revoked.contains(&key.id) {
+            return Err(format!("key {} has been revoked", key.id).into());
+        }
+        if self.keys().any(|known| known.id == key.id) {
+            return Err(format!("key {} is already trusted", key.id).into());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.{}", key.id, KEY_EXTENSION));
+        fs::write(&path, key.to_file())?;
+        self.keys.push((path, key));
+        Ok(())
+    }
+
+    /// Stop trusting a key, for good
+    pub fn revoke(&mut self, id: KeyId) -> Result<(), Box<dyn Error>> {
+        if self.revoked.contains(&id) {
+            return Err(format!("key {} is already revoked", id).into());
+        }
+        for (path, _) in self.keys.iter().filter(|(_, key)| key.id == id) {
+            fs::remove_file(path)?;
+        }
+        self.keys.retain(|(_, key)| key.id != id);
+        fs::create_dir_all(&self.dir)?;
+        self.revoked.push(id);
+        let list: String = self.revoked.iter().map(|id| format!("{}\n", id)).collect();
+        fs::write(self.dir.join(REVOKED_FILE), list)?;
+        Ok(())
+    }
+
+    /// Check `signature`, the contents of a .minisig file, over `data`
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<(), Box<dyn Error>> {
+        let signature = SignatureFile::parse(signature)?;
+        let id = signature.key_id;
+        if self.revoked.contains(&id) {
+            return Err(format!("signed with revoked key {}", id).into());
+        }
+        let key = self
+            .keys()
+            .find(|key| key.id == id)
+            .ok_or_else(|| format!("signed with unknown key {}; trust it with 'rpkg key add'", id))?;
+
+        let digest;
+        let message = if signature.hashed {
+            digest = Blake2b512::digest(data);
+            &digest[..]
+        } else {
+            data
+        };
+        key.key.verify(message, &signature.signature).map_err(|_| "bad signature")?;
+
+        let mut global = signature.signature.to_bytes().to_vec();
+        global.extend_from_slice(signature.trusted_comment.as_bytes());
+        key.key.verify(&global, &signature.global_signature).map_err(|_| "bad signature on the trusted comment")?;
+        Ok(())
+    }
+}
+
+/// Where a file's detached signature is, beside it
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(SIGNATURE_SUFFIX);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(keys: &[&SecretKey]) -> Keyring {
+        Keyring {
+            dir: PathBuf::new(),
+            keys: keys.iter().map(|key| (PathBuf::new(), key.public_key())).collect(),
+            revoked: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn verifies_what_was_signed() {
+        let key = SecretKey::generate().unwrap();
+        let signature = key.sign(b"package", "foo-1.0.rpkg");
+        keyring(&[&key]).verify(b"package", signature.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn rejects_changed_data() {
+        let key = SecretKey::generate().unwrap();
+        let signature = key.sign(b"package", "foo-1.0.rpkg");
+        assert!(keyring(&[&key]).verify(b"packagf", signature.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn rejects_changed_trusted_comment() {
+        let key = SecretKey::generate().unwrap();
+        let signature = key.sign(b"package", "foo-1.0.rpkg").replace("foo-1.0", "foo-9.9");
+        let error = keyring(&[&key]).verify(b"package", signature.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "bad signature on the trusted comment");
+    }
+
+    #[test]
+    fn rejects_unknown_and_revoked_keys() {
+        let key = SecretKey::generate().unwrap();
+        let other = SecretKey::generate().unwrap();
+        let signature = key.sign(b"package", "foo-1.0.rpkg");
+        assert!(keyring(&[&other]).verify(b"package", signature.as_bytes()).is_err());
+
+        let mut ring = keyring(&[&key]);
+        ring.revoked.push(key.id);
+        let error = ring.verify(b"package", signature.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("revoked"));
+    }
+
+    #[test]
+    fn keys_survive_their_files() {
+        let key = SecretKey::generate().unwrap();
+        let public = PublicKey::parse(&key.public_key().to_file()).unwrap();
+        assert_eq!(public.id, key.id);
+        assert_eq!(KeyId::parse(&key.id.to_string()), Some(key.id));
+
+        let file = key.to_file("").unwrap();
+        let secret = SecretKey::parse(&file, || Err("no password expected".into())).unwrap();
+        let signature = secret.sign(b"package", "foo-1.0.rpkg");
+        keyring(&[&key]).verify(b"package", signature.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn revoking_removes_the_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SecretKey::generate().unwrap();
+        let mut ring = Keyring::load(dir.path().to_str().unwrap()).unwrap();
+        ring.add(key.public_key()).unwrap();
+        assert!(ring.add(key.public_key()).is_err());
+        ring.revoke(key.id).unwrap();
+
+        let mut ring = Keyring::load(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(ring.keys().count(), 0);
+        assert_eq!(ring.revoked(), &[key.id]);
+        assert!(ring.add(key.public_key()).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+//! Transactions: changes to the installed files that apply whole or not at all
+//!
+//! An operation's packages are first unpacked into a staging directory,
+//! where nothing outside can see them. Committing runs every package's
+//! pre-install hook, moves the staged files into the root, and then runs
+//! the post-install hooks. Before each file is put in place the journal
+//! records it, and the file it replaces is copied into the transaction's
+//! backup directory, so the root as it was can always be put back. A
+//! failed hook or file undoes everything done so far. A transaction left
+//! pending by a crash is undone the next time one begins, and a committed
+//! one can be undone later with `rpkg rollback`.
+//!
+//! A package's hooks are the executables `.rpkg/pre-install` and
+//! `.rpkg/post-install` in its archive. They run in the sandbox, in the
+//! root, with RPKG_ROOT, RPKG_PACKAGE and RPKG_TRANSACTION set. Work that
+//! many packages would each do, like rebuilding a cache, is better left
+//! to a trigger, which runs once for the whole transaction.
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tar::Archive;
+use walkdir::WalkDir;
+use crate::config::{Config, HooksConfig};
+use crate::sandbox::Sandbox;
+
+pub const HOOK_DIR: &str = ".rpkg";
+const PRE_INSTALL: &str = "pre-install";
+const POST_INSTALL: &str = "post-install";
+const JOURNAL_FILE: &str = "journal.json";
+// One change per line, appended before the change is made
+const CHANGES_FILE: &str = "changes.log";
+const BACKUP_DIR: &str = "backup";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum State {
+    Pending,
+    Committed,
+    RolledBack,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Change {
+    /// A directory that did not exist before
+    CreatedDir { path: PathBuf },
+    /// A file that did not exist before, and the hash of what was put there
+    Created { path: PathBuf, sha256: String },
+    /// A file put in place of one kept in the backup directory
+    Replaced { path: PathBuf, sha256: String },
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::CreatedDir { path } | Change::Created { path, .. } | Change::Replaced { path, .. } => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub id: String,
+    pub operation: String,
+    pub packages: Vec<String>,
+    /// When it began, in seconds since the epoch
+    pub time: i64,
+    pub state: State,
+    pub root: PathBuf,
+    #[serde(skip)]
+    pub changes: Vec<Change>,
+}
+
+pub struct Transaction {
+    journal: Journal,
+    dir: PathBuf,
+    staging: tempfile::TempDir,
+    // Each package, and where it was unpacked
+    staged: Vec<(String, PathBuf)>,
+    changes: Option<File>,
+    hooks: HooksConfig,
+}
+
+fn transactions_dir(config: &Config) -> PathBuf {
+    Path::new(&config.general.db_path).join("transactions")
+}
+
+/// The SHA-256 of a file, or of where a symlink points
+pub fn sha256(path: &Path) -> Result<String, Box<dyn Error>> {
+    let metadata = fs::symlink_metadata(path)?;
+    let digest = if metadata.file_type().is_symlink() {
+        Sha256::digest(fs::read_link(path)?.as_os_str().as_encoded_bytes())
+    } else {
+        Sha256::digest(fs::read(path)?)
+    };
+    Ok(hex::encode(digest))
+}
+
+// What a package puts in the root, from where it was unpacked: every path
+// but its hooks, parents before children
+fn contents(staged: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let entries = WalkDir::new(staged)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || entry.file_name() != HOOK_DIR);
+    let mut paths = Vec::new();
+    for entry in entries {
+        paths.push(entry?.path().strip_prefix(staged)?.to_path_buf());
+    }
+    Ok(paths)
+}
+
+// Copy a file or symlink, as it is
+fn copy_entry(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    if fs::symlink_metadata(from)?.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+// Put a copy of `from` at `to` in one step, through a file beside `to`
+fn replace_with(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    let name = to.file_name().ok_or("no file name")?.to_string_lossy();
+    let temp = to.with_file_name(format!(".{}.rpkg-new", name));
+    let _ = fs::remove_file(&temp);
+    copy_entry(from, &temp)?;
+    fs::rename(&temp, to)?;
+    Ok(())
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+impl Journal {
+    fn load(dir: &Path) -> Result<Journal, Box<dyn Error>> {
+        let mut journal: Journal = serde_json::from_str(&fs::read_to_string(dir.join(JOURNAL_FILE))?)?;
+        if let Ok(log) = fs::read_to_string(dir.join(CHANGES_FILE)) {
+            // A crash may have cut the last line short
+            journal.changes = log.lines().map_while(|line| serde_json::from_str(line).ok()).collect();
+        }
+        Ok(journal)
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let temp = dir.join(format!("{}.new", JOURNAL_FILE));
+        fs::write(&temp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp, dir.join(JOURNAL_FILE))?;
+        Ok(())
+    }
+
+    /// Put back what the changes replaced, newest first, and take away
+    /// what they created
+    fn undo(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        for change in self.changes.iter().rev() {
+            match change {
+                Change::CreatedDir { path } => {
+                    // Left alone if something else has put files in it
+                    let _ = fs::remove_dir(self.root.join(path));
+                }
+                Change::Created { path, .. } => {
+                    let target = self.root.join(path);
+                    if exists(&target) {
+                        fs::remove_file(&target)?;
+                    }
+                }
+                Change::Replaced { path, .. } => {
+                    replace_with(&dir.join(BACKUP_DIR).join(path), &self.root.join(path))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Start a transaction, first undoing any that a crash left pending
+    pub fn begin(config: &Config, operation: &str) -> Result<Transaction, Box<dyn Error>> {
+        recover(config)?;
+
+        let base = transactions_dir(config);
+        let now = chrono::Local::now();
+        let stamp = now.format("%Y%m%d-%H%M%S").to_string();
+        let id = (1..)
+            .map(|n| if n == 1 { stamp.clone() } else { format!("{}.{}", stamp, n) })
+            .find(|id| !base.join(id).exists())
+            .unwrap();
+        let dir = base.join(&id);
+        fs::create_dir_all(&dir)?;
+
+        fs::create_dir_all(&config.cache.dir)?;
+        let staging = tempfile::Builder::new().prefix("rpkg-staging-").tempdir_in(&config.cache.dir)?;
+        let journal = Journal {
+            id,
+            operation: operation.to_string(),
+            packages: Vec::new(),
+            time: now.timestamp(),
+            state: State::Pending,
+            root: PathBuf::from(&config.general.root_dir),
+            changes: Vec::new(),
+        };
+        Ok(Transaction { journal, dir, staging, staged: Vec::new(), changes: None, hooks: config.hooks.clone() })
+    }
+
+    /// Unpack a package's archive into the staging directory; returns the
+    /// paths it installs, relative to the root
+    pub fn stage(&mut self, name: &str, archive: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let dest = self.staging.path().join(name);
+        fs::create_dir_all(&dest)?;
+        let mut archive = Archive::new(GzDecoder::new(File::open(archive)?));
+        archive.set_preserve_permissions(true);
+        // Entries that would land outside `dest` are skipped
+        archive.unpack(&dest).map_err(|e| format!("cannot unpack {}: {}", name, e))?;
+        let paths = contents(&dest)?;
+        self.staged.push((name.to_string(), dest));
+        Ok(paths)
+    }
+
+    fn record(&mut self, change: Change) -> Result<(), Box<dyn Error>> {
+        if self.changes.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(self.dir.join(CHANGES_FILE))?;
+            self.changes = Some(file);
+        }
+        let log = self.changes.as_mut().unwrap();
+        writeln!(log, "{}", serde_json::to_string(&change)?)?;
+        log.sync_data()?;
+        self.journal.changes.push(change);
+        Ok(())
+    }
+
+    fn run_hook(&self, package: &str, staged: &Path, hook: &str) -> Result<(), Box<dyn Error>> {
+        let script = staged.join(HOOK_DIR).join(hook);
+        if !script.exists() {
+            return Ok(());
+        }
+        let status = Sandbox::new(&script, &self.hooks)
+            .and_then(|sandbox| {
+                sandbox
+                    .writable(&self.journal.root)
+                    .current_dir(&self.journal.root)
+                    .env("RPKG_ROOT", &self.journal.root)
+                    .env("RPKG_PACKAGE", package)
+                    .env("RPKG_TRANSACTION", &self.journal.id)
+                    .run()
+            })
+            .map_err(|e| format!("cannot run the {} {} hook: {}", package, hook, e))?;
+        if !status.success() {
+            return Err(format!("the {} {} hook failed ({})", package, hook, status).into());
+        }
+        Ok(())
+    }
+
+    fn run_hooks(&self, hook: &str) -> Result<(), Box<dyn Error>> {
+        for (package, staged) in &self.staged {
+            self.run_hook(package, staged, hook)?;
+        }
+        Ok(())
+    }
+
+    fn place(&mut self, staged: &Path, relative: &Path) -> Result<(), Box<dyn Error>> {
+        let target = self.journal.root.join(relative);
+        let is_dir = fs::symlink_metadata(staged)?.is_dir();
+        if is_dir {
+            if !exists(&target) {
+                self.record(Change::CreatedDir { path: relative.to_path_buf() })?;
+                fs::create_dir(&target)?;
+            }
+            return Ok(());
+        }
+
+        let sha256 = sha256(staged)?;
+        if exists(&target) {
+            if fs::symlink_metadata(&target)?.is_dir() {
+                return Err(format!("{} is a directory", target.display()).into());
+            }
+            // A file two packages both install is backed up the first time,
+            // so the backup is always what was there before
+            let backup = self.dir.join(BACKUP_DIR).join(relative);
+            if !exists(&backup) {
+                fs::create_dir_all(backup.parent().unwrap())?;
+                copy_entry(&target, &backup)?;
+            }
+            self.record(Change::Replaced { path: relative.to_path_buf(), sha256 })?;
+        } else {
+            self.record(Change::Created { path: relative.to_path_buf(), sha256 })?;
+        }
+        replace_with(staged, &target)
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn Error>> {
+        let staged = self.staged.clone();
+        for (_, root) in &staged {
+            for relative in contents(root)? {
+                self.place(&root.join(&relative), &relative)
+                    .map_err(|e| format!("cannot install /{}: {}", relative.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply everything staged, or, if any of it fails, nothing
+    pub fn commit(mut self) -> Result<Journal, Box<dyn Error>> {
+        self.journal.packages = self.staged.iter().map(|(name, _)| name.clone()).collect();
+        self.journal.save(&self.dir)?;
+
+        if let Err(e) = self.run_hooks(PRE_INSTALL) {
+            self.journal.state = State::RolledBack;
+            self.journal.save(&self.dir)?;
+            return Err(format!("{}; nothing was changed", e).into());
+        }
+
+        let result = self.apply().and_then(|_| self.run_hooks(POST_INSTALL));
+        if let Err(e) = result {
+            self.journal.undo(&self.dir).map_err(|undo| {
+                format!("{}; rolling back transaction {} failed too: {}", e, self.journal.id, undo)
+            })?;
+            self.journal.state = State::RolledBack;
+            self.journal.save(&self.dir)?;
+            return Err(format!("{}; transaction {} rolled back", e, self.journal.id).into());
+        }
+
+        self.journal.state = State::Committed;
+        self.journal.save(&self.dir)?;
+        Ok(self.journal.clone())
+    }
+}
+
+/// Undo the transactions a crash left pending
+pub fn recover(config: &Config) -> Result<(), Box<dyn Error>> {
+    for journal in list(config)?.into_iter().filter(|journal| journal.state == State::Pending) {
+        let dir = transactions_dir(config).join(&journal.id);
+        journal.undo(&dir)?;
+        let journal = Journal { state: State::RolledBack, ..journal };
+        journal.save(&dir)?;
+        crate::display::print_warning(&format!("Interrupted transaction {} was rolled back", journal.id));
+    }
+    Ok(())
+}
+
+/// Every transaction, the oldest first
+pub fn list(config: &Config) -> Result<Vec<Journal>, Box<dyn Error>> {
+    let base = transactions_dir(config);
+    let mut journals = Vec::new();
+    if base.is_dir() {
+        for entry in fs::read_dir(&base)? {
+            let dir = entry?.path();
+            if dir.join(JOURNAL_FILE).exists() {
+                journals.push(Journal::load(&dir)?);
+            }
+        }
+    }
+    journals.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+    Ok(journals)
+}
+
+/// Undo a committed transaction. Files changed since it are not touched
+/// unless `force`; its packages' hooks are not run.
+pub fn rollback(config: &Config, id: &str, force: bool) -> Result<Journal, Box<dyn Error>> {
+    let dir = transactions_dir(config).join(id);
+    if !dir.join(JOURNAL_FILE).exists() {
+        return Err(format!("no transaction {}", id).into());
+    }
+    let journal = Journal::load(&dir)?;
+    if journal.state != State::Committed {
+        return Err(format!("transaction {} was not committed", id).into());
+    }
+
+    if !force {
+        let changed: Vec<String> = journal
+            .changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::Created { path, sha256: hash } | Change::Replaced { path, sha256: hash } => {
+                    let target = journal.root.join(path);
+                    let same = exists(&target) && sha256(&target).is_ok_and(|current| current == *hash);
+                    (!same).then(|| format!("/{}", path.display()))
+                }
+                Change::CreatedDir { .. } => None,
+            })
+            .collect();
+        if !changed.is_empty() {
+            return Err(format!(
+                "changed since transaction {}: {}; use --force to roll back anyway",
+                id,
+                changed.join(", ")
+            )
+            .into());
+        }
+    }
+
+    journal.undo(&dir)?;
+    let journal = Journal { state: State::RolledBack, ..journal };
+    journal.save(&dir)?;
+    Ok(journal)
+}
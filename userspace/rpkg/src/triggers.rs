@@ -0,0 +1,213 @@
+//! Triggers: work done once at the end of a transaction for everything it
+//! changed under the paths they watch, in place of each package doing it
+//! in a hook of its own
+//!
+//! A trigger is a TOML file, `<name>.toml`, that a package installs in
+//! `usr/share/rpkg/triggers` under the root. One of the same name in
+//! `etc/rpkg/triggers` takes its place, and turns it off if it has no
+//! `exec`:
+//!
+//! ```toml
+//! description = "Updating the font cache"
+//! paths = ["/usr/share/fonts"]
+//! exec = ["fc-cache", "-s"]
+//! ```
+//!
+//! A path is watched along with everything under it. Triggers run after a
+//! transaction is committed or rolled back, as hooks do: in the sandbox,
+//! in the root, with RPKG_ROOT, RPKG_TRANSACTION and RPKG_TRIGGER set. One
+//! that fails is reported, but the transaction stands.
+
+use colored::*;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+use crate::display::print_warning;
+use crate::sandbox::Sandbox;
+use crate::transaction::Journal;
+
+// Under the root, where packages install triggers
+const PACKAGE_DIR: &str = "usr/share/rpkg/triggers";
+// and where the administrator overrides them
+const LOCAL_DIR: &str = "etc/rpkg/triggers";
+const EXTENSION: &str = "toml";
+
+#[derive(Debug, Deserialize)]
+pub struct Trigger {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// The program and its arguments
+    #[serde(default)]
+    pub exec: Vec<String>,
+}
+
+impl Trigger {
+    /// Whether a change to `path`, relative to the root, fires it
+    pub fn watches(&self, path: &Path) -> bool {
+        self.paths.iter().any(|watched| path.starts_with(watched.strip_prefix("/").unwrap_or(watched)))
+    }
+
+    fn run(&self, config: &Config, journal: &Journal) -> Result<(), Box<dyn Error>> {
+        let status = Sandbox::new(&self.exec[0], &config.hooks)?
+            .args(&self.exec[1..])
+            .writable(&journal.root)
+            .current_dir(&journal.root)
+            .env("RPKG_ROOT", &journal.root)
+            .env("RPKG_TRANSACTION", &journal.id)
+            .env("RPKG_TRIGGER", &self.name)
+            .run()?;
+        if !status.success() {
+            return Err(format!("failed ({})", status).into());
+        }
+        Ok(())
+    }
+}
+
+/// The triggers installed under `root`, in order of name
+pub fn load(root: &Path) -> Result<Vec<Trigger>, Box<dyn Error>> {
+    let mut triggers = BTreeMap::new();
+    for dir in [PACKAGE_DIR, LOCAL_DIR] {
+        let dir = root.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != EXTENSION) {
+                continue;
+            }
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let trigger = fs::read_to_string(&path)
+                .map_err(Box::<dyn Error>::from)
+                .and_then(|text| Ok(toml::from_str::<Trigger>(&text)?));
+            match trigger {
+                Ok(trigger) => {
+                    triggers.insert(name.clone(), Trigger { name, ..trigger });
+                }
+                // One broken trigger is no reason to skip the rest
+                Err(e) => print_warning(&format!("ignoring trigger {}: {}", path.display(), e)),
+            }
+        }
+    }
+    Ok(triggers.into_values().filter(|trigger| !trigger.exec.is_empty()).collect())
+}
+
+/// Run, once each, the triggers watching anything a transaction changed
+pub fn run(config: &Config, journal: &Journal) -> Result<(), Box<dyn Error>> {
+    let fired: Vec<Trigger> = load(&journal.root)?
+        .into_iter()
+        .filter(|trigger| journal.changes.iter().any(|change| trigger.watches(change.path())))
+        .collect();
+    if fired.is_empty() {
+        return Ok(());
+    }
+
+    println!("{} Running triggers...", "::".blue().bold());
+    for trigger in &fired {
+        let description = if trigger.description.is_empty() { &trigger.name } else { &trigger.description };
+        println!("  {} {}", "•".dimmed(), description);
+        if let Err(e) = trigger.run(config, journal) {
+            print_warning(&format!("trigger {}: {}", trigger.name, e));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Change, State};
+
+    fn install(root: &Path, dir: &str, name: &str, text: &str) {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), text).unwrap();
+    }
+
+    fn trigger(paths: &[&str]) -> Trigger {
+        Trigger {
+            name: "fonts".to_string(),
+            description: String::new(),
+            paths: paths.iter().map(PathBuf::from).collect(),
+            exec: vec!["true".to_string()],
+        }
+    }
+
+    #[test]
+    fn watches_paths_and_everything_under_them() {
+        let fonts = trigger(&["/usr/share/fonts"]);
+        assert!(fonts.watches(Path::new("usr/share/fonts")));
+        assert!(fonts.watches(Path::new("usr/share/fonts/dejavu/DejaVuSans.ttf")));
+        assert!(!fonts.watches(Path::new("usr/share/fonts-extra/a.ttf")));
+        assert!(!fonts.watches(Path::new("usr/share")));
+        // Written without the leading slash, it means the same
+        assert!(trigger(&["usr/share/fonts"]).watches(Path::new("usr/share/fonts/a.ttf")));
+        assert!(!trigger(&[]).watches(Path::new("usr/share/fonts/a.ttf")));
+    }
+
+    #[test]
+    fn local_triggers_take_the_place_of_packaged_ones() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        install(root, PACKAGE_DIR, "fonts.toml", "paths = [\"/usr/share/fonts\"]\nexec = [\"fc-cache\"]\n");
+        install(root, PACKAGE_DIR, "icons.toml", "paths = [\"/usr/share/icons\"]\nexec = [\"gtk-update-icon-cache\"]\n");
+        install(root, PACKAGE_DIR, "mime.toml", "paths = [\"/usr/share/mime\"]\nexec = [\"update-mime-database\"]\n");
+        install(root, LOCAL_DIR, "icons.toml", "paths = [\"/usr/share/icons\"]\nexec = [\"icons\", \"--quiet\"]\n");
+        // No exec turns the packaged one off
+        install(root, LOCAL_DIR, "mime.toml", "description = \"off\"\n");
+
+        let triggers = load(root).unwrap();
+        let names: Vec<&str> = triggers.iter().map(|trigger| trigger.name.as_str()).collect();
+        assert_eq!(names, ["fonts", "icons"]);
+        assert_eq!(triggers[1].exec, ["icons", "--quiet"]);
+    }
+
+    #[test]
+    fn skips_broken_and_other_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        install(root, PACKAGE_DIR, "broken.toml", "paths = \"/usr\n");
+        install(root, PACKAGE_DIR, "wrong.toml", "paths = 3\nexec = [\"true\"]\n");
+        install(root, PACKAGE_DIR, "README", "paths = [\"/\"]\nexec = [\"true\"]\n");
+        install(root, PACKAGE_DIR, "ok.toml", "exec = [\"true\"]\n");
+
+        let triggers = load(root).unwrap();
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(triggers[0].name, "ok");
+        assert!(load(&root.join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn runs_each_fired_trigger_once() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let exec = "exec = [\"/bin/sh\", \"-c\", \"echo $RPKG_TRIGGER $RPKG_TRANSACTION >> ran\"]\n";
+        install(root, PACKAGE_DIR, "fonts.toml", &format!("paths = [\"/usr/share/fonts\"]\n{}", exec));
+        install(root, PACKAGE_DIR, "icons.toml", &format!("paths = [\"/usr/share/icons\"]\n{}", exec));
+
+        let created = |path: &str| Change::Created { path: PathBuf::from(path), sha256: String::new() };
+        let journal = Journal {
+            id: "42".to_string(),
+            operation: "install".to_string(),
+            packages: vec!["dejavu".to_string()],
+            time: 0,
+            state: State::Committed,
+            root: root.to_path_buf(),
+            changes: vec![
+                Change::CreatedDir { path: PathBuf::from("usr/share/fonts/dejavu") },
+                created("usr/share/fonts/dejavu/DejaVuSans.ttf"),
+                created("usr/share/fonts/dejavu/DejaVuSerif.ttf"),
+                created("usr/share/doc/dejavu/README"),
+            ],
+        };
+        run(&Config::default(), &journal).unwrap();
+        assert_eq!(fs::read_to_string(root.join("ran")).unwrap(), "fonts 42\n");
+    }
+}
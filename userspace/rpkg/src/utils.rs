@@ -1,7 +1,31 @@
+use std::collections::{BTreeMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use colored::*;
-use crate::config::Config;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::{Config, RepositoryConfig};
+use crate::database::{Dependency, FileRecord, Index, IndexEntry, InstalledPackage, LocalDatabase};
+use crate::display::print_warning;
+use crate::exit::Exit;
+use crate::resolver::{self, Candidate, Problem, Request, Solution};
+use crate::signing::{signature_path, Keyring, SIGNATURE_SUFFIX};
+
+// A repository's package index, at the top of its URL
+pub const INDEX_FILE: &str = "index.json";
+
+// A search is a regular expression, matched regardless of case
+fn search_pattern(query: &str) -> Result<Regex, Box<dyn Error>> {
+    Ok(RegexBuilder::new(query)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("bad search pattern '{}': {}", query, e))?)
+}
 
 pub fn confirm_action(prompt: &str) -> Result<bool, Box<dyn Error>> {
     print!("{} {} [Y/n] ", "::".blue().bold(), prompt);
@@ -25,32 +49,354 @@ impl PackageManager {
         })
     }
     
-    pub fn resolve_install(&self, packages: &[String], no_deps: bool) -> Result<Resolution, Box<dyn Error>> {
-        Ok(Resolution::default())
+    /// Every package the synchronized repositories have, and every one
+    /// installed
+    pub fn candidates(&self) -> Result<Vec<Candidate>, Box<dyn Error>> {
+        let local = LocalDatabase::load(&self.config.general.db_path)?;
+        let mut candidates = Vec::new();
+        for repo in self.config.repositories.iter().filter(|repo| repo.enabled) {
+            let path = self.sync_path(&repo.name);
+            if !path.exists() {
+                print_warning(&format!("{} has not been synchronized; run 'rpkg update'", repo.name));
+                continue;
+            }
+            for entry in Index::load(&path)?.packages {
+                let installed = local.get(&entry.name).is_some_and(|package| {
+                    package.repository == repo.name && package.entry.version == entry.version
+                });
+                candidates.push(Candidate { entry, repository: repo.name.clone(), priority: repo.priority, installed });
+            }
+        }
+        // Installed versions that no repository has any more
+        for package in local.packages {
+            let known = candidates.iter().any(|candidate| candidate.installed && candidate.entry.name == package.entry.name);
+            if !known {
+                candidates.push(Candidate {
+                    entry: package.entry,
+                    repository: package.repository,
+                    priority: u32::MAX,
+                    installed: true,
+                });
+            }
+        }
+        Ok(candidates)
+    }
+    
+    pub fn resolve_install(
+        &self,
+        packages: &[String],
+        no_deps: bool,
+        reinstall: bool,
+    ) -> Result<Resolution, Box<dyn Error>> {
+        let request = Request {
+            install: packages.to_vec(),
+            no_deps,
+            allow_downgrade: self.config.security.allow_downgrade,
+            ..Request::default()
+        };
+        self.resolve(request, reinstall)
+    }
+    
+    /// Upgrade `packages`, or everything installed but `ignore`, to the
+    /// newest versions that fit together
+    pub fn resolve_upgrade(&self, packages: &[String], ignore: &[String]) -> Result<Resolution, Box<dyn Error>> {
+        let local = LocalDatabase::load(&self.config.general.db_path)?;
+        if let Some(name) = packages.iter().find(|name| local.get(name).is_none()) {
+            return Err(format!("{} is not installed", name).into());
+        }
+        let upgrade = if packages.is_empty() {
+            local.packages.iter().map(|package| package.entry.name.clone()).collect()
+        } else {
+            packages.to_vec()
+        };
+        let held = |name: &String| ignore.contains(name) || self.config.locks.hold.contains(name);
+        let request = Request {
+            upgrade: upgrade.into_iter().filter(|name| !held(name)).collect(),
+            hold: ignore.to_vec(),
+            allow_downgrade: self.config.security.allow_downgrade,
+            ..Request::default()
+        };
+        self.resolve(request, false)
+    }
+    
+    /// The pins in the configuration
+    pub fn pins(&self) -> Result<Vec<Dependency>, Box<dyn Error>> {
+        self.config.locks.pin.iter()
+            .map(|(name, requirement)| {
+                let requirement = semver::VersionReq::parse(requirement)
+                    .map_err(|e| format!("bad pin for {}: {}", name, e))?;
+                Ok(Dependency { name: name.clone(), requirement: Some(requirement) })
+            })
+            .collect()
+    }
+    
+    fn resolve(&self, mut request: Request, reinstall: bool) -> Result<Resolution, Box<dyn Error>> {
+        request.hold.extend(self.config.locks.hold.iter().cloned());
+        request.pins = self.pins()?;
+        let candidates = self.candidates()?;
+        match resolver::resolve(&candidates, &request) {
+            Ok(solution) => Ok(Resolution::new(&candidates, &solution, reinstall)),
+            Err(problems) => {
+                let mut resolution = Resolution::default();
+                let mut messages = Vec::new();
+                let mut not_found = false;
+                for problem in problems {
+                    match problem {
+                        Problem::NotFound(spec) => {
+                            not_found = true;
+                            messages.push(format!("target not found: {}", spec))
+                        }
+                        Problem::Conflict(reason) => resolution.conflicts.push(Conflict { reason }),
+                        Problem::Other(message) => messages.push(message),
+                    }
+                }
+                if resolution.conflicts.is_empty() {
+                    let message = format!("cannot resolve dependencies:\n  {}", messages.join("\n  "));
+                    // Asking for what no repository has is not a conflict
+                    if not_found {
+                        return Err(message.into());
+                    }
+                    return Err(Exit::Conflict(message).into());
+                }
+                Ok(resolution)
+            }
+        }
     }
     
     pub fn search_installed(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error>> {
-        Ok(Vec::new())
+        let pattern = search_pattern(query)?;
+        let local = LocalDatabase::load(&self.config.general.db_path)?;
+        Ok(local.packages.into_iter()
+            .filter(|package| pattern.is_match(&package.entry.name) || pattern.is_match(&package.entry.description))
+            .map(|package| SearchResult {
+                repository: package.repository,
+                name: package.entry.name,
+                version: package.entry.version.to_string(),
+                description: package.entry.description,
+                is_installed: true,
+            })
+            .collect())
     }
     
     pub fn search_repository(&self, query: &str, repo: &str) -> Result<Vec<SearchResult>, Box<dyn Error>> {
-        Ok(Vec::new())
+        let repo = self.config.repositories.iter()
+            .find(|candidate| candidate.name == repo)
+            .ok_or_else(|| format!("no repository named {}", repo))?;
+        self.search_repositories(query, &[repo])
     }
     
     pub fn search_all(&self, query: &str) -> Result<Vec<SearchResult>, Box<dyn Error>> {
-        Ok(Vec::new())
+        let repos: Vec<&RepositoryConfig> = self.config.repositories.iter().filter(|repo| repo.enabled).collect();
+        self.search_repositories(query, &repos)
+    }
+    
+    // The newest version of each package in `repos` whose name or
+    // description matches
+    fn search_repositories(&self, query: &str, repos: &[&RepositoryConfig]) -> Result<Vec<SearchResult>, Box<dyn Error>> {
+        let pattern = search_pattern(query)?;
+        let local = LocalDatabase::load(&self.config.general.db_path)?;
+        let mut results = Vec::new();
+        for repo in repos {
+            let path = self.sync_path(&repo.name);
+            if !path.exists() {
+                print_warning(&format!("{} has not been synchronized; run 'rpkg update'", repo.name));
+                continue;
+            }
+            let mut newest: BTreeMap<String, IndexEntry> = BTreeMap::new();
+            for entry in Index::load(&path)?.packages {
+                if !pattern.is_match(&entry.name) && !pattern.is_match(&entry.description) {
+                    continue;
+                }
+                if newest.get(&entry.name).is_none_or(|known| known.version < entry.version) {
+                    newest.insert(entry.name.clone(), entry);
+                }
+            }
+            results.extend(newest.into_values().map(|entry| SearchResult {
+                repository: repo.name.clone(),
+                is_installed: local.get(&entry.name).is_some(),
+                name: entry.name,
+                version: entry.version.to_string(),
+                description: entry.description,
+            }));
+        }
+        Ok(results)
+    }
+    
+    fn client(&self) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(self.config.network.timeout_seconds as u64));
+        if let Some(proxy) = &self.config.network.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder.build()?)
     }
     
-    pub fn download_package(&self, pkg: &PackageInfo) -> Result<(), Box<dyn Error>> {
+    // The file at `url`, or None if the server has no such file
+    fn fetch(&self, url: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let response = self.client()?.get(url).send()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.bytes()?.to_vec()))
+    }
+    
+    /// Check a downloaded file against its detached signature. A file with
+    /// no signature is refused unless `allow_unsigned`; one with a bad
+    /// signature, or one by an unknown or revoked key, always is.
+    pub fn check_signature(
+        &self,
+        name: &str,
+        data: &[u8],
+        signature: Option<&[u8]>,
+        allow_unsigned: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.config.security.verify_signatures {
+            return Ok(());
+        }
+        let Some(signature) = signature else {
+            if allow_unsigned {
+                print_warning(&format!("{} is not signed", name));
+                return Ok(());
+            }
+            return Err(format!("{} is not signed; use --allow-unsigned to accept it anyway", name).into());
+        };
+        let keyring = Keyring::load(&self.config.security.keyring_dir)?;
+        keyring.verify(data, signature).map_err(|e| format!("{}: {}", name, e))?;
         Ok(())
     }
     
+    /// Where a repository's index is kept once synchronized
+    pub fn sync_path(&self, repo: &str) -> PathBuf {
+        Path::new(&self.config.general.db_path).join("sync").join(format!("{}.json", repo))
+    }
+    
+    /// Fetch a repository's index and check its signature before keeping
+    /// it. Returns false if the copy kept was already current.
+    pub fn sync_repository(
+        &self,
+        repo: &RepositoryConfig,
+        force: bool,
+        allow_unsigned: bool,
+    ) -> Result<bool, Box<dyn Error>> {
+        let url = format!("{}/{}", repo.url.trim_end_matches('/'), INDEX_FILE);
+        let index = self.fetch(&url)?.ok_or_else(|| format!("{} has no {}", repo.name, INDEX_FILE))?;
+        let path = self.sync_path(&repo.name);
+        if !force && fs::read(&path).is_ok_and(|current| current == index) {
+            return Ok(false);
+        }
+        let signature = self.fetch(&format!("{}{}", url, SIGNATURE_SUFFIX))?;
+        self.check_signature(&format!("{} index", repo.name), &index, signature.as_deref(), allow_unsigned)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, &index)?;
+        Ok(true)
+    }
+    
+    /// Download a package into the cache, with its signature, once the
+    /// signature has been checked
+    pub fn download_package(&self, pkg: &PackageInfo, allow_unsigned: bool) -> Result<PathBuf, Box<dyn Error>> {
+        let repo = self.config.repositories.iter()
+            .find(|repo| repo.name == pkg.repository)
+            .ok_or_else(|| format!("{} comes from unknown repository {}", pkg.name, pkg.repository))?;
+        let url = format!("{}/{}", repo.url.trim_end_matches('/'), pkg.filename);
+        let archive = self.fetch(&url)?.ok_or_else(|| format!("{} is missing from {}", pkg.filename, repo.name))?;
+        let signature = self.fetch(&format!("{}{}", url, SIGNATURE_SUFFIX))?;
+        self.check_signature(&pkg.filename, &archive, signature.as_deref(), allow_unsigned)?;
+        if let (true, Some(expected)) = (self.config.security.verify_checksums, &pkg.sha256) {
+            if hex::encode(Sha256::digest(&archive)) != *expected {
+                return Err(format!("{} does not match its checksum in the index", pkg.filename).into());
+            }
+        }
+        
+        let path = Path::new(&self.config.cache.dir).join(&pkg.filename);
+        fs::create_dir_all(&self.config.cache.dir)?;
+        fs::write(&path, &archive)?;
+        if let Some(signature) = signature {
+            fs::write(signature_path(&path), signature)?;
+        }
+        Ok(path)
+    }
+    
+    /// Installed packages a repository has a newer version of
+    pub fn outdated(&self) -> Result<Vec<Outdated>, Box<dyn Error>> {
+        let local = LocalDatabase::load(&self.config.general.db_path)?;
+        let candidates = self.candidates()?;
+        let pins = self.pins()?;
+        let mut outdated = Vec::new();
+        for package in &local.packages {
+            let name = &package.entry.name;
+            let newer: Vec<&Candidate> = candidates.iter()
+                .filter(|candidate| candidate.entry.name == *name && candidate.entry.version > package.entry.version)
+                .collect();
+            let Some(newest) = newer.iter().map(|candidate| candidate.entry.version).max() else {
+                continue;
+            };
+            let pin = pins.iter().find(|pin| pin.name == *name);
+            let held = self.config.locks.hold.contains(name);
+            let upgrade_to = match pin {
+                _ if held => None,
+                Some(pin) => newer.iter()
+                    .filter(|candidate| pin.matches(&candidate.entry))
+                    .map(|candidate| candidate.entry.version)
+                    .max(),
+                None => Some(newest),
+            };
+            let lock = if held {
+                Some(String::from("held"))
+            } else {
+                self.config.locks.pin.get(name).map(|requirement| format!("pinned to {}", requirement))
+            };
+            outdated.push(Outdated {
+                name: name.clone(),
+                installed: package.entry.version,
+                newest,
+                upgrade_to,
+                lock,
+            });
+        }
+        Ok(outdated)
+    }
+    
+    // Record a package in the local database once its files are in place
+    fn record_installed(&self, pkg: &PackageInfo, explicit: bool) -> Result<(), Box<dyn Error>> {
+        let index = Index::load(&self.sync_path(&pkg.repository))?;
+        let entry = index.packages.into_iter()
+            .find(|entry| entry.name == pkg.name && entry.version == pkg.version)
+            .ok_or_else(|| format!("{} is not in {} any more", pkg.name, pkg.repository))?;
+        let mut local = LocalDatabase::load(&self.config.general.db_path)?;
+        let explicit = explicit || local.get(&pkg.name).is_some_and(|package| package.explicit);
+        local.record(InstalledPackage {
+            entry,
+            repository: pkg.repository.clone(),
+            explicit,
+            install_date: chrono::Utc::now().timestamp(),
+        });
+        local.save()
+    }
+    
     pub fn install_package(&self, pkg: &PackageInfo) -> Result<(), Box<dyn Error>> {
-        Ok(())
+        self.record_installed(pkg, true)
     }
     
+    /// Record a package pulled in for another; an upgrade of one installed
+    /// explicitly stays explicit
     pub fn install_as_dependency(&self, pkg: &PackageInfo) -> Result<(), Box<dyn Error>> {
-        Ok(())
+        self.record_installed(pkg, false)
+    }
+    
+    /// Record the files a package installed, as they are now in the root
+    pub fn record_files(&self, pkg: &PackageInfo, paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+        let root = Path::new(&self.config.general.root_dir);
+        let files = paths.iter()
+            .map(|path| FileRecord::read(root, path))
+            .collect::<Result<Vec<_>, _>>()?;
+        LocalDatabase::load(&self.config.general.db_path)?.set_files(&pkg.name, &files)
+    }
+    
+    pub fn forget_package(&self, pkg: &PackageInfo) -> Result<(), Box<dyn Error>> {
+        let mut local = LocalDatabase::load(&self.config.general.db_path)?;
+        local.remove(&pkg.name);
+        local.remove_files(&pkg.name)?;
+        local.save()
     }
 }
 
@@ -61,9 +407,63 @@ pub struct Resolution {
     pub to_remove: Vec<PackageInfo>,
     pub conflicts: Vec<Conflict>,
     pub suggestions: Vec<Suggestion>,
+    /// The packages asked for by name
+    pub explicit: HashSet<String>,
+    /// Each package that changes, and why
+    pub explanation: Vec<(String, String)>,
 }
 
 impl Resolution {
+    fn new(candidates: &[Candidate], solution: &Solution, reinstall: bool) -> Resolution {
+        let mut resolution = Resolution::default();
+        let installed = |name: &str| candidates.iter().find(|candidate| candidate.installed && candidate.entry.name == name);
+        let selected: Vec<&Candidate> = solution.selected.iter().map(|&(index, _)| &candidates[index]).collect();
+        
+        for (&(index, ref why), candidate) in solution.selected.iter().zip(&selected) {
+            let requested = solution.requested.contains(&index);
+            if requested {
+                resolution.explicit.insert(candidate.entry.name.clone());
+            }
+            match installed(&candidate.entry.name) {
+                Some(_) if candidate.installed && !(reinstall && requested) => continue,
+                Some(old) if !candidate.installed => {
+                    resolution.to_upgrade.push((PackageInfo::from(old), PackageInfo::from(*candidate)))
+                }
+                _ => resolution.to_install.push(PackageInfo::from(*candidate)),
+            }
+            resolution.explanation.push((candidate.entry.to_string(), why.clone()));
+            
+            for optional in &candidate.entry.optional {
+                let wanted = Dependency::parse(&optional.name)
+                    .is_ok_and(|dependency| selected.iter().any(|other| dependency.matches(&other.entry)));
+                let suggested = resolution.suggestions.iter().any(|suggestion| suggestion.name == optional.name);
+                if !wanted && !suggested {
+                    resolution.suggestions.push(Suggestion {
+                        name: optional.name.clone(),
+                        reason: format!("{} (for {})", optional.reason, candidate.entry.name),
+                    });
+                }
+            }
+        }
+        
+        for old in candidates.iter().filter(|candidate| candidate.installed) {
+            if selected.iter().all(|candidate| candidate.entry.name != old.entry.name) {
+                let replacement = selected.iter().find(|candidate| {
+                    candidate.entry.replaces.iter().any(|spec| {
+                        Dependency::parse(spec).is_ok_and(|dependency| dependency.matches(&old.entry))
+                    })
+                });
+                let why = match replacement {
+                    Some(candidate) => format!("replaced by {}", candidate.entry),
+                    None => String::from("no longer wanted"),
+                };
+                resolution.explanation.push((old.entry.to_string(), why));
+                resolution.to_remove.push(PackageInfo::from(old));
+            }
+        }
+        resolution
+    }
+    
     pub fn total_download_size(&self) -> u64 {
         self.to_install.iter().map(|p| p.size).sum::<u64>() +
         self.to_upgrade.iter().map(|(_, p)| p.size).sum::<u64>()
@@ -74,28 +474,101 @@ impl Resolution {
     }
 }
 
+#[derive(Clone)]
 pub struct PackageInfo {
     pub name: String,
     pub version: Version,
+    /// Where it comes from, and its archive's name there
+    pub repository: String,
+    pub filename: String,
     pub size: u64,
     pub installed_size: u64,
+    pub sha256: Option<String>,
 }
 
+impl From<&Candidate> for PackageInfo {
+    fn from(candidate: &Candidate) -> PackageInfo {
+        PackageInfo {
+            name: candidate.entry.name.clone(),
+            version: candidate.entry.version,
+            repository: candidate.repository.clone(),
+            filename: candidate.entry.filename.clone(),
+            size: candidate.entry.size,
+            installed_size: candidate.entry.installed_size,
+            sha256: candidate.entry.sha256.clone(),
+        }
+    }
+}
+
+impl From<&InstalledPackage> for PackageInfo {
+    fn from(package: &InstalledPackage) -> PackageInfo {
+        PackageInfo {
+            name: package.entry.name.clone(),
+            version: package.entry.version,
+            repository: package.repository.clone(),
+            filename: package.entry.filename.clone(),
+            size: package.entry.size,
+            installed_size: package.entry.installed_size,
+            sha256: package.entry.sha256.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct Version {
     pub major: u32,
     pub minor: u32,
     pub patch: u32,
 }
 
-impl ToString for Version {
-    fn to_string(&self) -> String {
-        format!("{}.{}.{}", self.major, self.minor, self.patch)
+impl Version {
+    /// `major.minor.patch`, where missing parts are 0
+    pub fn parse(text: &str) -> Option<Version> {
+        let mut parts = text.split('.');
+        let mut next = || parts.next().map_or(Some(0), |part| part.parse().ok());
+        let version = Version { major: next()?, minor: next()?, patch: next()? };
+        parts.next().is_none().then_some(version)
+    }
+    
+    pub fn to_semver(self) -> semver::Version {
+        semver::Version::new(self.major as u64, self.minor as u64, self.patch as u64)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = String;
+    
+    fn try_from(text: String) -> Result<Version, String> {
+        Version::parse(&text).ok_or_else(|| format!("'{}' is not a version", text))
+    }
+}
+
+impl From<Version> for String {
+    fn from(version: Version) -> String {
+        version.to_string()
     }
 }
 
+#[derive(Serialize)]
+pub struct Outdated {
+    pub name: String,
+    pub installed: Version,
+    pub newest: Version,
+    /// What an upgrade would bring it to, which a hold or pin may keep
+    /// short of the newest
+    pub upgrade_to: Option<Version>,
+    /// The hold or pin on it
+    pub lock: Option<String>,
+}
+
 pub struct Conflict {
-    pub package1: String,
-    pub package2: String,
     pub reason: String,
 }
 
@@ -104,10 +577,12 @@ pub struct Suggestion {
     pub reason: String,
 }
 
+#[derive(Serialize)]
 pub struct SearchResult {
     pub repository: String,
     pub name: String,
     pub version: String,
     pub description: String,
+    #[serde(rename = "installed")]
     pub is_installed: bool,
 }
\ No newline at end of file